@@ -0,0 +1,126 @@
+//! What this agent can do, as listed in `AgentRegister`
+//!
+//! The list is worked out from what initializes on the machine rather than
+//! what the build contains: a Wayland desktop without a RemoteDesktop portal
+//! or uinput access can be watched but not controlled, and a headless server
+//! has no screen at all. The server refuses sessions that need something
+//! missing here instead of letting them fail on the agent. Names match the
+//! server's `AgentCapability`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{debug, info, warn};
+
+use crate::config::ClientConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentCapability {
+    ScreenCapture,
+    InputControl,
+    FileTransfer,
+    Terminal,
+    Clipboard,
+    AudioCapture,
+    MultiMonitor,
+    Recording,
+    Elevation,
+}
+
+impl fmt::Display for AgentCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The wire name, so logs read like the registration
+        let name = serde_json::to_value(self).ok();
+        f.write_str(name.as_ref().and_then(|name| name.as_str()).unwrap_or("unknown"))
+    }
+}
+
+/// Parts of the agent set up before registration that decide a capability
+pub struct AgentFeatures {
+    pub clipboard: bool,
+    pub elevation: bool,
+}
+
+/// Probe the machine for what sessions it can serve
+pub async fn detect(config: &ClientConfig, features: AgentFeatures) -> Vec<AgentCapability> {
+    let (capture, input) = display_access().await;
+    let terminal = config.terminal.enabled && crate::terminal::Pty::available();
+
+    let capabilities = [
+        (AgentCapability::ScreenCapture, capture),
+        (AgentCapability::InputControl, capture && input),
+        (AgentCapability::FileTransfer, config.file_transfer.enabled),
+        (AgentCapability::Terminal, terminal),
+        (AgentCapability::Clipboard, features.clipboard),
+        // No audio path exists yet
+        (AgentCapability::AudioCapture, false),
+        (AgentCapability::MultiMonitor, capture),
+        (AgentCapability::Recording, capture),
+        (AgentCapability::Elevation, features.elevation),
+    ];
+    let capabilities: Vec<_> = capabilities.into_iter().filter(|(_, found)| *found).map(|(capability, _)| capability).collect();
+
+    let names: Vec<_> = capabilities.iter().map(ToString::to_string).collect();
+    info!("Agent capabilities: {}", names.join(", "));
+    capabilities
+}
+
+/// Whether the screen can be captured and input injected
+#[cfg(target_os = "linux")]
+async fn display_access() -> (bool, bool) {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        // Portal calls block on DBus
+        let probe = tokio::task::spawn_blocking(|| {
+            let portal = crate::capture::wayland::portal::ScreenCastPortal::new()?;
+            let uinput = crate::input::linux::uinput::check_access();
+            if let Err(e) = &uinput {
+                debug!("No uinput fallback: {}", e);
+            }
+            Ok::<_, crate::error::GhostLinkError>(portal.supports_remote_desktop() || uinput.is_ok())
+        });
+        match probe.await {
+            Ok(Ok(input)) => (true, input),
+            Ok(Err(e)) => {
+                warn!("No ScreenCast portal, this desktop cannot be shared: {}", e);
+                (false, false)
+            }
+            Err(e) => {
+                warn!("Portal probe failed: {}", e);
+                (false, false)
+            }
+        }
+    } else if std::env::var_os("DISPLAY").is_some() {
+        // X11 input goes through xdotool
+        let xdotool = tokio::process::Command::new("xdotool").arg("version").output().await;
+        if let Err(e) = &xdotool {
+            warn!("xdotool not available, this desktop can be viewed but not controlled: {}", e);
+        }
+        (true, xdotool.is_ok())
+    } else {
+        info!("No graphical session, screen sharing is unavailable");
+        (false, false)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn display_access() -> (bool, bool) {
+    (true, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_use_the_server_names() {
+        let names = serde_json::to_value([
+            AgentCapability::ScreenCapture,
+            AgentCapability::InputControl,
+            AgentCapability::MultiMonitor,
+            AgentCapability::Recording,
+        ])
+        .unwrap();
+        assert_eq!(names, serde_json::json!(["screen_capture", "input_control", "multi_monitor", "recording"]));
+        assert_eq!(AgentCapability::FileTransfer.to_string(), "file_transfer");
+    }
+}
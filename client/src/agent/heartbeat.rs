@@ -1,6 +1,14 @@
-use tokio::time::{Duration, Instant};
+use std::path::Path;
+use sysinfo::{Disk, Disks, System};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant, Interval};
 use tracing::{debug, info, warn};
 
+use crate::config::reload::LiveSettings;
+use crate::policy::ServerPolicy;
+use crate::resources::ResourceUsage;
+use crate::updater::UpdateReport;
+
 
 /// Manages heartbeat communication with the server
 pub struct HeartbeatManager {
@@ -80,57 +88,200 @@ pub enum HeartbeatHealth {
 }
 
 /// Heartbeat message sent to server
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+///
+/// Kept to a handful of numbers; it goes out every heartbeat interval from
+/// every agent.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct HeartbeatMessage {
     pub timestamp: u64,
     pub agent_id: String,
     pub uptime: u64,
     pub active_sessions: u32,
-    pub system_load: Option<f32>,
-    pub memory_usage: Option<u64>,
+    /// CPU usage across all cores, in percent
+    pub cpu_usage: f32,
+    pub memory_used: u64,
+    pub memory_total: u64,
+    /// Bytes used on the disk holding the system root
+    pub disk_used: u64,
+    pub disk_total: u64,
+    /// 1, 5 and 15 minute load averages; not available on Windows
+    pub load_average: Option<[f64; 3]>,
+    /// Outcome of the last self-update, until it is settled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<UpdateReport>,
+    /// The agent crashed since its last heartbeat was sent
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub crashed: bool,
+    /// What capture and recording take against the agent's limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceUsage>,
+}
+
+/// When heartbeats are due: every `heartbeat_interval` of the local
+/// config, which a reload may change, unless the server's policy sets one
+pub struct HeartbeatSchedule {
+    policy: watch::Receiver<ServerPolicy>,
+    live: watch::Receiver<LiveSettings>,
+    secs: u64,
+    interval: Interval,
+}
+
+impl HeartbeatSchedule {
+    pub fn new(mut policy: watch::Receiver<ServerPolicy>, mut live: watch::Receiver<LiveSettings>) -> Self {
+        let secs = Self::configured(&mut policy, &mut live);
+        Self {
+            policy,
+            live,
+            secs,
+            interval: tokio::time::interval(Duration::from_secs(secs)),
+        }
+    }
+
+    fn configured(policy: &mut watch::Receiver<ServerPolicy>, live: &mut watch::Receiver<LiveSettings>) -> u64 {
+        let local = live.borrow_and_update().heartbeat_interval;
+        policy.borrow_and_update().heartbeat_interval_secs.unwrap_or(local).max(1)
+    }
+
+    /// Wait until the next heartbeat is due; a changed interval restarts
+    /// the wait from the moment it changed
+    pub async fn tick(&mut self) {
+        loop {
+            tokio::select! {
+                _ = self.interval.tick() => return,
+                Ok(()) = self.policy.changed() => {}
+                Ok(()) = self.live.changed() => {}
+            }
+            let secs = Self::configured(&mut self.policy, &mut self.live);
+            if secs != self.secs {
+                info!("Heartbeat interval changed to {}s", secs);
+                self.secs = secs;
+                let period = Duration::from_secs(secs);
+                self.interval = tokio::time::interval_at(Instant::now() + period, period);
+            }
+        }
+    }
 }
 
-impl HeartbeatMessage {
-    pub fn new(agent_id: String, active_sessions: u32) -> Self {
+/// Samples the system for each heartbeat.
+///
+/// CPU usage is measured over the time since the previous sample, so the
+/// heartbeat task keeps one collector for its whole lifetime.
+pub struct MetricsCollector {
+    system: System,
+    disks: Disks,
+}
+
+impl MetricsCollector {
+    pub fn new() -> Self {
+        let mut system = System::new();
+        system.refresh_cpu_usage();
+        Self {
+            system,
+            disks: Disks::new_with_refreshed_list(),
+        }
+    }
+
+    /// Build a heartbeat from a fresh sample
+    pub fn heartbeat(&mut self, agent_id: String, active_sessions: u32) -> HeartbeatMessage {
+        self.system.refresh_cpu_usage();
+        self.system.refresh_memory();
+        self.disks.refresh();
+
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
+        let (disk_used, disk_total) = primary_disk(&self.disks)
+            .map(|disk| (disk.total_space().saturating_sub(disk.available_space()), disk.total_space()))
+            .unwrap_or((0, 0));
+        let load_average = if cfg!(windows) {
+            None
+        } else {
+            let load = System::load_average();
+            Some([load.one, load.five, load.fifteen])
+        };
 
-        let uptime = Self::get_system_uptime();
-        let (system_load, memory_usage) = Self::get_system_metrics();
-
-        Self {
+        HeartbeatMessage {
             timestamp,
             agent_id,
-            uptime,
+            uptime: System::uptime(),
             active_sessions,
-            system_load,
-            memory_usage,
+            cpu_usage: self.system.global_cpu_info().cpu_usage(),
+            memory_used: self.system.used_memory(),
+            memory_total: self.system.total_memory(),
+            disk_used,
+            disk_total,
+            load_average,
+            update: None,
+            crashed: false,
+            resources: crate::resources::usage(),
         }
     }
+}
 
-    fn get_system_uptime() -> u64 {
-        use sysinfo::System;
-        System::uptime()
+impl Default for MetricsCollector {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn get_system_metrics() -> (Option<f32>, Option<u64>) {
-        use sysinfo::System;
-        
-        let mut sys = System::new();
-        sys.refresh_memory();
-        
-        // Memory usage as percentage
-        let memory_usage = if sys.total_memory() > 0 {
-            Some(((sys.total_memory() - sys.available_memory()) * 100) / sys.total_memory())
-        } else {
-            None
+/// Disk mounted at the system root, or the largest disk if none is
+fn primary_disk(disks: &Disks) -> Option<&Disk> {
+    let root = if cfg!(windows) {
+        format!("{}\\", std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string()))
+    } else {
+        "/".to_string()
+    };
+
+    disks.iter()
+        .find(|disk| disk.mount_point() == Path::new(&root))
+        .or_else(|| disks.iter().max_by_key(|disk| disk.total_space()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_round_trip() {
+        let heartbeat = HeartbeatMessage {
+            timestamp: 1_700_000_000,
+            agent_id: "agent-1".to_string(),
+            uptime: 3600,
+            active_sessions: 2,
+            cpu_usage: 12.5,
+            memory_used: 4 << 30,
+            memory_total: 16 << 30,
+            disk_used: 100 << 30,
+            disk_total: 500 << 30,
+            load_average: Some([0.5, 0.25, 0.125]),
+            update: Some(UpdateReport {
+                status: "failed".to_string(),
+                version: "1.1.0".to_string(),
+                error: Some("Did not reach the relay in 3 starts".to_string()),
+            }),
+            crashed: true,
+            resources: Some(ResourceUsage {
+                cpu_percent: 29.5,
+                cpu_budget_percent: Some(30),
+                memory_used: 48 << 20,
+                memory_limit: Some(256 << 20),
+                frames_throttled: 1200,
+                frames_shed: 3,
+            }),
         };
 
-        // System load (simplified - would need platform-specific implementation)
-        let system_load = None; // TODO: Implement per-platform
+        let json = serde_json::to_string(&heartbeat).unwrap();
+        assert!(json.len() < 512, "heartbeat grew to {} bytes", json.len());
+        let decoded: HeartbeatMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, heartbeat);
+    }
 
-        (system_load, memory_usage)
+    #[test]
+    fn test_collector_reports_memory() {
+        let heartbeat = MetricsCollector::new().heartbeat("agent-1".to_string(), 0);
+        assert!(heartbeat.memory_total > 0);
+        assert!(heartbeat.memory_used <= heartbeat.memory_total);
+        assert!(heartbeat.disk_used <= heartbeat.disk_total);
     }
 }
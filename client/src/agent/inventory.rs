@@ -0,0 +1,302 @@
+//! Hardware and software inventory
+//!
+//! The agent reports an [`Inventory`] when it registers and every
+//! `inventory.interval_secs` after, daily by default; the server keeps the
+//! latest report and the history of what changed. Collecting is cheap: a
+//! sysinfo sample, the adapters `network` already reads, and one lookup of
+//! the serial number and console user, from `/sys/class/dmi` on Linux,
+//! `ioreg` on macOS and CIM through PowerShell on Windows. The server's
+//! `inventory_enabled` policy or `inventory.enabled` turn reporting off.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use sysinfo::{Disks, System};
+use tracing::debug;
+
+use crate::network::{self, NetworkInterface};
+
+/// How long a serial number or console user lookup may take
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Values firmware puts where the vendor never filled in a serial or UUID
+const PLACEHOLDERS: [&str; 8] = [
+    "",
+    "0",
+    "none",
+    "default string",
+    "to be filled by o.e.m.",
+    "system serial number",
+    "not specified",
+    "03000200-0400-0500-0006-000700080009",
+];
+
+/// Inventory reporting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InventoryPolicy {
+    /// Report inventory; the server's policy takes precedence
+    pub enabled: bool,
+    /// Seconds between reports after the one on registration
+    pub interval_secs: u64,
+}
+
+impl Default for InventoryPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// What the agent reports about its machine
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Inventory {
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    /// Kernel version, or the build number on Windows
+    pub os_build: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    /// SMBIOS system UUID
+    pub system_uuid: Option<String>,
+    /// User logged on at the console, if any
+    pub logged_in_user: Option<String>,
+    pub cpu: Option<String>,
+    pub cpu_cores: Option<u32>,
+    /// Installed memory, in bytes
+    pub memory_total: Option<u64>,
+    pub interfaces: Vec<NetworkInterface>,
+    pub disks: Vec<DiskInventory>,
+    pub agent_version: Option<String>,
+}
+
+/// A fixed disk and its capacity
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DiskInventory {
+    pub mount_point: String,
+    pub name: Option<String>,
+    pub file_system: Option<String>,
+    /// Capacity, in bytes
+    pub total_bytes: u64,
+}
+
+/// Serial number, system UUID, make and console user of the machine
+#[derive(Debug, Default, PartialEq)]
+struct Platform {
+    manufacturer: Option<String>,
+    model: Option<String>,
+    serial_number: Option<String>,
+    system_uuid: Option<String>,
+    logged_in_user: Option<String>,
+}
+
+/// Take an inventory of this machine
+pub async fn collect() -> Inventory {
+    let mut system = System::new();
+    system.refresh_memory();
+    system.refresh_cpu();
+
+    let disks = Disks::new_with_refreshed_list()
+        .iter()
+        .filter(|disk| !disk.is_removable() && disk.total_space() > 0)
+        .map(|disk| DiskInventory {
+            mount_point: disk.mount_point().display().to_string(),
+            name: meaningful(&disk.name().to_string_lossy()),
+            file_system: meaningful(&disk.file_system().to_string_lossy()),
+            total_bytes: disk.total_space(),
+        })
+        .collect();
+    let platform = platform().await;
+
+    Inventory {
+        os_name: System::name(),
+        os_version: System::os_version(),
+        os_build: System::kernel_version(),
+        manufacturer: platform.manufacturer,
+        model: platform.model,
+        serial_number: platform.serial_number,
+        system_uuid: platform.system_uuid,
+        logged_in_user: platform.logged_in_user,
+        cpu: system.cpus().first().and_then(|cpu| meaningful(cpu.brand())),
+        cpu_cores: system.physical_core_count().map(|cores| cores as u32),
+        memory_total: Some(system.total_memory()).filter(|total| *total > 0),
+        interfaces: network::interfaces(),
+        disks,
+        agent_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    }
+}
+
+/// `value` trimmed, unless it is empty or a firmware placeholder
+fn meaningful(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!PLACEHOLDERS.contains(&value.to_lowercase().as_str())).then(|| value.to_string())
+}
+
+/// Output of `program`, if it ran successfully in time
+async fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(LOOKUP_TIMEOUT, output).await {
+        Ok(Ok(output)) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Ok(Ok(output)) => {
+            debug!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+            None
+        }
+        Ok(Err(e)) => {
+            debug!("{} is not available: {}", program, e);
+            None
+        }
+        Err(_) => {
+            debug!("{} did not answer within {:?}", program, LOOKUP_TIMEOUT);
+            None
+        }
+    }
+}
+
+/// SMBIOS values from `/sys/class/dmi/id`; serial and UUID need root
+#[cfg(target_os = "linux")]
+async fn platform() -> Platform {
+    let dmi = |name: &str| {
+        std::fs::read_to_string(format!("/sys/class/dmi/id/{}", name))
+            .ok()
+            .and_then(|value| meaningful(&value))
+    };
+    Platform {
+        manufacturer: dmi("sys_vendor"),
+        model: dmi("product_name"),
+        serial_number: dmi("product_serial"),
+        system_uuid: dmi("product_uuid"),
+        logged_in_user: run("who", &[]).await.and_then(|output| parse_who(&output)),
+    }
+}
+
+#[cfg(target_os = "macos")]
+async fn platform() -> Platform {
+    let ioreg = run("ioreg", &["-rd1", "-c", "IOPlatformExpertDevice"]).await.unwrap_or_default();
+    // The login window owns the console while nobody is logged in
+    let console_user = run("stat", &["-f", "%Su", "/dev/console"]).await
+        .and_then(|user| meaningful(&user))
+        .filter(|user| user != "root");
+    Platform {
+        manufacturer: ioreg_value(&ioreg, "manufacturer"),
+        model: ioreg_value(&ioreg, "model"),
+        serial_number: ioreg_value(&ioreg, "IOPlatformSerialNumber"),
+        system_uuid: ioreg_value(&ioreg, "IOPlatformUUID"),
+        logged_in_user: console_user,
+    }
+}
+
+#[cfg(windows)]
+async fn platform() -> Platform {
+    const QUERY: &str = "$b = Get-CimInstance Win32_BIOS; \
+        $p = Get-CimInstance Win32_ComputerSystemProduct; \
+        $c = Get-CimInstance Win32_ComputerSystem; \
+        @{ serial = $b.SerialNumber; uuid = $p.UUID; manufacturer = $c.Manufacturer; model = $c.Model; user = $c.UserName } \
+        | ConvertTo-Json -Compress";
+    run("powershell.exe", &["-NoProfile", "-NonInteractive", "-Command", QUERY]).await
+        .map(|output| parse_cim(&output))
+        .unwrap_or_default()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+async fn platform() -> Platform {
+    Platform::default()
+}
+
+/// User logged on at a local seat from the output of `who`, or the first
+/// one listed if nobody is
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_who(output: &str) -> Option<String> {
+    let sessions: Vec<(&str, &str)> = output.lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            Some((columns.next()?, columns.next().unwrap_or_default()))
+        })
+        .collect();
+    sessions.iter()
+        .find(|(_, line)| line.starts_with(':') || line.starts_with("tty") || line.starts_with("seat"))
+        .or(sessions.first())
+        .map(|(user, _)| user.to_string())
+}
+
+/// Value of `key` in `ioreg` output, where strings read `"key" = "value"`
+/// and data reads `"key" = <"value">`
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn ioreg_value(output: &str, key: &str) -> Option<String> {
+    let prefix = format!("\"{}\" = ", key);
+    output.lines()
+        .find_map(|line| line.trim().strip_prefix(prefix.as_str()))
+        .map(|value| value.trim_start_matches('<').trim_end_matches('>').trim_matches('"'))
+        .and_then(meaningful)
+}
+
+/// Platform values from the JSON the Windows CIM query prints
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_cim(output: &str) -> Platform {
+    let json: serde_json::Value = serde_json::from_str(output.trim()).unwrap_or_default();
+    let value = |key: &str| json.get(key).and_then(|value| value.as_str()).and_then(meaningful);
+    Platform {
+        manufacturer: value("manufacturer"),
+        model: value("model"),
+        serial_number: value("serial"),
+        system_uuid: value("uuid"),
+        logged_in_user: value("user"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placeholders_are_dropped() {
+        assert_eq!(meaningful(" 5CG1234XYZ\n"), Some("5CG1234XYZ".to_string()));
+        assert_eq!(meaningful("To Be Filled By O.E.M."), None);
+        assert_eq!(meaningful("\n"), None);
+    }
+
+    #[test]
+    fn test_parse_who_prefers_a_local_seat() {
+        let output = "\
+            alice    pts/0        2026-10-17 08:55 (10.0.0.4)\n\
+            bob      :0           2026-10-17 08:50 (:0)\n";
+        assert_eq!(parse_who(output), Some("bob".to_string()));
+        assert_eq!(parse_who("alice    pts/0        2026-10-17 08:55\n"), Some("alice".to_string()));
+        assert_eq!(parse_who(""), None);
+    }
+
+    #[test]
+    fn test_ioreg_values() {
+        let output = r#"
++-o J314sAP  <class IOPlatformExpertDevice, id 0x100000201, registered, matched, active, busy 0 (0 ms), retain 37>
+    {
+      "manufacturer" = <"Apple Inc.">
+      "model" = <"MacBookPro18,1">
+      "IOPlatformUUID" = "8A2B6C1E-0F3D-4A5B-9C7D-1E2F3A4B5C6D"
+      "IOPlatformSerialNumber" = "C02ZK1ABMD6R"
+    }
+"#;
+        assert_eq!(ioreg_value(output, "manufacturer").as_deref(), Some("Apple Inc."));
+        assert_eq!(ioreg_value(output, "model").as_deref(), Some("MacBookPro18,1"));
+        assert_eq!(ioreg_value(output, "IOPlatformSerialNumber").as_deref(), Some("C02ZK1ABMD6R"));
+        assert_eq!(ioreg_value(output, "IOPlatformUUID").as_deref(), Some("8A2B6C1E-0F3D-4A5B-9C7D-1E2F3A4B5C6D"));
+        assert_eq!(ioreg_value(output, "board-id"), None);
+    }
+
+    #[test]
+    fn test_parse_cim() {
+        let output = r#"{"serial":"5CG1234XYZ","uuid":"4C4C4544-0042-3510-8052-B4C04F4E4B32","manufacturer":"HP","model":"EliteBook 840 G8","user":"ACME\\jdoe"}"#;
+        assert_eq!(parse_cim(output), Platform {
+            manufacturer: Some("HP".to_string()),
+            model: Some("EliteBook 840 G8".to_string()),
+            serial_number: Some("5CG1234XYZ".to_string()),
+            system_uuid: Some("4C4C4544-0042-3510-8052-B4C04F4E4B32".to_string()),
+            logged_in_user: Some("ACME\\jdoe".to_string()),
+        });
+        assert_eq!(parse_cim(r#"{"serial":"Default string","user":null}"#), Platform::default());
+    }
+}
@@ -1,31 +1,151 @@
 #![allow(dead_code)]
 
-use anyhow::{Context, Result};
+use crate::error::{ConnectionError, Context, ElevationError, GhostLinkError, PreflightError, Result, SessionError};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::time::Instant;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify, RwLock};
 use tokio::time::{interval, Duration};
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
+use crate::capture::displays::DisplayEvent;
+use crate::capture::negotiation::ViewerCapabilities;
+use crate::chat::{ChatManager, ChatSpool};
+use crate::clipboard::{self, ClipboardService};
+use crate::config::reload::LiveSettings;
 use crate::config::ClientConfig;
-use crate::connection::RelayConnection;
-use crate::session::{Session, SessionType};
+use crate::connection::monitor_protocol::MonitorControlMessage;
+use crate::connection::compression;
+use crate::connection::datagram::LaneTimings;
+use crate::connection::hybrid::{ConnectionSettings, ConnectionType, HybridConnectionManager, RelayLink};
+use crate::connection::quality::{ProbeTracker, QualityReport};
+use crate::connection::udp_lane::{self, UdpLane};
+use crate::connection::{P2PConnectionInfo, RelayConnection, RelayMessage};
+use crate::control::pause::{AccessPause, PauseStore};
+use crate::control::{self, AgentStatus, ControlCommand, ControlRequest, ControlResponse, SessionSummary};
+use crate::elevation::{self, ElevationBackend};
+use crate::file_transfer::archive::{self, ArchiveRequest};
+use crate::file_transfer::browser::{self, FsError};
+use crate::file_transfer::transfer::TransferState;
+use crate::file_transfer::{FileBrowser, FileTransferManager, FsRequest, TransferProgress};
+use crate::input::pointer_mode::PointerCaptureDetector;
+use crate::input::InputMode;
+use crate::logging::crash;
+use crate::network;
+use crate::permissions::{self, PermissionState};
+use crate::policy::{PolicyStore, ServerPolicy};
+use crate::recording::OperatorInfo;
+use crate::registry::{self, RegistryService};
+use crate::resources;
+use crate::session::banner::{self, BannerColors, BannerDecision, BannerGate, BannerOutcome, SessionBanner};
+use crate::session::consent::{ConsentOutcome, ConsentPolicy};
+use crate::session::input_indicator::{self, ControlledSession, IndicatorLifecycle};
+use crate::session::preflight::FailureCode;
+use crate::session::{PlatformSessions, SessionOpener, SessionReadiness, SessionType};
+use crate::terminal::TerminalManager;
+use crate::toolbox::queue::{self, CommandQueue};
+use crate::updater::host::SystemHost;
+use crate::updater::Updater;
 
+pub mod capabilities;
 pub mod heartbeat;
 // pub mod installer;
+pub mod inventory;
 pub mod session_manager;
 
 // Re-export SessionManager
 pub use session_manager::SessionManager;
+use heartbeat::{HeartbeatSchedule, MetricsCollector};
 
 /// Main agent orchestrator that manages all client operations
 pub struct Agent {
     config: ClientConfig,
     relay_connection: Arc<RwLock<Option<RelayConnection>>>,
     session_manager: Arc<SessionManager>,
+    /// Opens requested sessions; the platform's capture and input outside tests
+    sessions: Arc<dyn SessionOpener>,
     shutdown_tx: mpsc::Sender<()>,
     shutdown_rx: mpsc::Receiver<()>,
+    server_rx: Option<mpsc::Receiver<RelayMessage>>,
+    registry: Option<Arc<RegistryService>>,
+    elevation: Option<Arc<dyn ElevationBackend>>,
+    clipboard: Option<Arc<ClipboardService>>,
+    /// Whether the clipboard is shared at all, as config and policy say
+    clipboard_enabled: Arc<AtomicBool>,
+    file_transfers: Arc<FileTransferManager>,
+    transfer_rx: Option<mpsc::Receiver<RelayMessage>>,
+    terminals: Arc<TerminalManager>,
+    terminal_rx: Option<mpsc::Receiver<RelayMessage>>,
+    chat: Arc<ChatManager>,
+    chat_rx: Option<mpsc::Receiver<RelayMessage>>,
+    /// Session requests waiting for the end user to accept their banner or
+    /// consent to the session
+    banners: Arc<BannerGate<HeldSession>>,
+    banner_rx: Option<mpsc::Receiver<BannerDecision>>,
+    /// Direct-or-relayed transport per session that negotiated P2P
+    transports: Arc<RwLock<HashMap<String, HybridConnectionManager>>>,
+    /// Latency probes this agent sent, per session
+    probes: Arc<parking_lot::Mutex<HashMap<String, ProbeTracker>>>,
+    /// Sessions whose technician asked for compressed frames
+    frame_compression: Arc<parking_lot::Mutex<HashSet<String>>>,
+    updater: Option<Arc<Updater>>,
+    /// Set when an update wants the agent restarted once it has shut down
+    restart_pending: Arc<AtomicBool>,
+    /// Values a config reload changed, laid over `config`
+    live: watch::Receiver<LiveSettings>,
+    /// Last policy from the server, laid over `config` and `live`
+    policy: watch::Sender<ServerPolicy>,
+    /// Where the policy is kept between runs; unset for temporary agents
+    policy_store: Option<PolicyStore>,
+    /// Tools and scripts deployments sent, until the server has their result
+    command_queue: Arc<CommandQueue>,
+    /// Woken when a deployment sends a command
+    commands_ready: Arc<Notify>,
+    /// Requests from the tray, answered by the event loop
+    control_tx: mpsc::Sender<ControlCommand>,
+    control_rx: Option<mpsc::Receiver<ControlCommand>>,
+    /// Where the control API listens; unset for temporary agents
+    control_endpoint: Option<PathBuf>,
+    /// Remote access paused by the end user
+    pause: parking_lot::Mutex<AccessPause>,
+    /// Support code the end user joined from the tray, answered once the
+    /// relay takes or refuses it
+    pending_join: parking_lot::Mutex<Option<(String, oneshot::Sender<ControlResponse>)>>,
 }
 
+/// A session request held while the end user is asked about it
+struct HeldSession {
+    request: RelayMessage,
+    /// Set when the question is consent to the session rather than a banner
+    consent: Option<ConsentPolicy>,
+}
+
+/// How often the agent probes each session's link
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Probe rounds between quality reports
+const PROBES_PER_REPORT: u32 = 3;
+
+/// How often terminals are checked for the idle timeout
+const TERMINAL_IDLE_CHECK: Duration = Duration::from_secs(30);
+
+/// How often replies left by `chat send` are picked up
+const CHAT_SPOOL_POLL: Duration = Duration::from_secs(1);
+
+/// How often sessions are checked for the idle timeout
+const SESSION_IDLE_CHECK: Duration = Duration::from_secs(30);
+
+/// How long shutdown waits for the relay to acknowledge ended sessions
+const SESSION_END_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often results the server has not accepted are sent again
+const COMMAND_RETRY: Duration = Duration::from_secs(30);
+
+/// How long the notice of a dropped file stays up
+const DROP_NOTICE_SECONDS: u32 = 8;
+
 #[derive(Debug, Clone)]
 pub enum AgentMessage {
     Connect,
@@ -38,115 +158,1924 @@ pub enum AgentMessage {
 impl Agent {
     pub fn new(config: ClientConfig) -> Result<Self> {
         let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let registry = registry::platform_backend()
+            .map(|backend| Arc::new(RegistryService::new(backend, config.registry.clone())));
+        let (transfer_tx, transfer_rx) = mpsc::channel(32);
+        let file_transfers = Arc::new(FileTransferManager::new(config.file_transfer.clone(), transfer_tx));
+        let (terminal_tx, terminal_rx) = mpsc::channel(256);
+        let terminals = Arc::new(TerminalManager::new(config.terminal.clone(), terminal_tx));
+        let (chat_tx, chat_rx) = mpsc::channel(64);
+        let chat_sender = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| config.hostname.clone());
+        let chat = Arc::new(ChatManager::new(chat_sender, chat_tx));
+        let (banners, banner_rx) = BannerGate::new(banner::platform_display());
+        // Policy can turn sharing on later, so the backend is set up either way
+        let clipboard = clipboard::platform_backend()
+            .map(|backend| Arc::new(ClipboardService::new(backend, &config.clipboard)));
+        let clipboard_enabled = Arc::new(AtomicBool::new(config.clipboard.enabled));
+        let restart_pending = Arc::new(AtomicBool::new(false));
+        let host = Arc::new(SystemHost::new(shutdown_tx.clone(), Arc::clone(&restart_pending)));
+        let updater = Updater::new(&config, host).unwrap_or_else(|e| {
+            warn!("Auto-update disabled: {:#}", e);
+            None
+        });
+        
+        let live = watch::channel(LiveSettings::of(&config)).1;
+        let (control_tx, control_rx) = mpsc::channel(8);
         
         Ok(Self {
             config,
+            live,
             relay_connection: Arc::new(RwLock::new(None)),
             session_manager: Arc::new(SessionManager::new()),
+            sessions: Arc::new(PlatformSessions),
             shutdown_tx,
             shutdown_rx,
+            server_rx: None,
+            registry,
+            elevation: elevation::platform_backend(),
+            clipboard,
+            clipboard_enabled,
+            file_transfers,
+            transfer_rx: Some(transfer_rx),
+            terminals,
+            terminal_rx: Some(terminal_rx),
+            chat,
+            chat_rx: Some(chat_rx),
+            banners: Arc::new(banners),
+            banner_rx: Some(banner_rx),
+            transports: Arc::new(RwLock::new(HashMap::new())),
+            probes: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+            frame_compression: Arc::new(parking_lot::Mutex::new(HashSet::new())),
+            updater: updater.map(Arc::new),
+            restart_pending,
+            policy: watch::Sender::new(ServerPolicy::default()),
+            policy_store: None,
+            command_queue: Arc::new(CommandQueue::in_memory()),
+            commands_ready: Arc::new(Notify::new()),
+            control_tx,
+            control_rx: Some(control_rx),
+            control_endpoint: None,
+            pause: parking_lot::Mutex::new(AccessPause::default()),
+            pending_join: parking_lot::Mutex::new(None),
         })
     }
 
-    /// Start the agent and all background tasks
-    pub async fn start(&mut self) -> Result<()> {
-        info!("Starting AtlasConnect Agent");
+    /// Keep server policy in `store`, starting from the policy stored there
+    pub fn with_policy_store(mut self, store: PolicyStore) -> Self {
+        if let Some(policy) = store.load() {
+            info!("Applying stored policy from {}", store.path().display());
+            self.policy.send_replace(policy);
+            self.apply_policy_gates(&self.effective_config());
+        }
+        self.policy_store = Some(store);
+        self
+    }
+
+    /// Keep deployment commands in `queue`, picking up those a previous run
+    /// left behind
+    pub fn with_command_queue(mut self, queue: CommandQueue) -> Self {
+        self.command_queue = Arc::new(queue);
+        self
+    }
+
+    /// Serve the tray's control API at `endpoint`, keeping the end user's
+    /// access pause in `pause`
+    pub fn with_control(mut self, endpoint: PathBuf, pause: PauseStore) -> Self {
+        let pause = AccessPause::with_store(pause);
+        if let Some(until) = pause.until(chrono::Utc::now()) {
+            info!("Remote access is paused until {}", until);
+        }
+        self.pause = parking_lot::Mutex::new(pause);
+        self.control_endpoint = Some(endpoint);
+        self
+    }
+
+    /// Follow config file changes from `live` while the agent runs
+    pub fn with_live_settings(mut self, live: watch::Receiver<LiveSettings>) -> Self {
+        self.live = live;
+        self
+    }
+
+    /// The local config, as last reloaded, with the server's policy laid
+    /// over it
+    pub fn effective_config(&self) -> ClientConfig {
+        let mut config = self.config.clone();
+        self.live.borrow().apply(&mut config);
+        self.policy.borrow().apply(&mut config);
+        config
+    }
+
+    /// Take on a policy from the server: keep it for the next start, switch
+    /// clipboard and file transfer, and cap the frame rate and bandwidth of
+    /// running sessions. New sessions and the heartbeat pick it up from there.
+    async fn apply_policy(&self, policy: ServerPolicy) {
+        if let Some(store) = &self.policy_store {
+            if let Err(e) = store.save(&policy) {
+                warn!("Failed to store policy: {:#}", e);
+            }
+        }
+        self.policy.send_replace(policy);
+
+        let config = self.effective_config();
+        self.apply_policy_gates(&config);
+        self.retune_sessions(&config).await;
+        self.announce_pause().await;
+        info!("Policy applied: max {} fps, heartbeat every {}s", config.encoding.adaptive.max_fps, config.heartbeat_interval);
+    }
+
+    /// Cap the frame rate and bandwidth of running sessions as `config` says
+    async fn retune_sessions(&self, config: &ClientConfig) {
+        for session_id in self.session_manager.list_sessions().await {
+            if let Some(session) = self.session_manager.get_session(&session_id).await {
+                session.set_max_fps(config.encoding.adaptive.max_fps).await;
+                session.set_bandwidth_ceiling(config.encoding.max_bandwidth_kbps).await;
+            }
+        }
+    }
+
+    /// Hold the end user's pause to the current policy and tell the relay
+    /// about it. The relay sends policy after every registration, so a
+    /// pause outlives reconnects.
+    async fn announce_pause(&self) {
+        let now = chrono::Utc::now();
+        let limit = self.policy.borrow().user_pause_limit();
+        let (changed, until) = {
+            let mut pause = self.pause.lock();
+            (pause.hold_to(limit, now), pause.until(now))
+        };
+        if changed {
+            info!("Policy changed the remote access pause, now {}", until.map_or("lifted".to_string(), |until| format!("until {}", until)));
+        }
+        if changed || until.is_some() {
+            if let Err(e) = self.send_to_server(RelayMessage::AccessPaused { until }).await {
+                warn!("Failed to tell the relay about the access pause: {}", e);
+            }
+        }
+    }
+
+    fn apply_policy_gates(&self, config: &ClientConfig) {
+        resources::apply(&config.resources);
+        self.clipboard_enabled.store(config.clipboard.enabled, Ordering::Relaxed);
+        self.file_transfers.set_enabled(config.file_transfer.enabled);
+        self.file_transfers.set_drops_enabled(config.file_transfer.drops_enabled);
+    }
+
+    /// Start the agent and all background tasks
+    pub async fn start(&mut self) -> Result<()> {
+        info!("Starting AtlasConnect Agent");
+        crash::set_config(&self.config);
+        resources::apply(&self.effective_config().resources);
+        
+        // Count this start against a pending update, rolling it back if needed
+        if let Some(updater) = &self.updater {
+            if let Err(e) = updater.on_startup() {
+                error!("Failed to settle pending update: {:#}", e);
+            }
+        }
+        
+        // Start heartbeat task
+        self.start_heartbeat_task().await?;
+        
+        // Start clipboard watcher
+        self.start_clipboard_task();
+        
+        // Start link quality probes and reports
+        self.start_quality_task();
+        
+        // Start file transfer forwarder
+        self.start_transfer_task();
+        
+        // Start terminal output forwarder and idle timeout
+        self.start_terminal_task();
+        
+        // Start chat forwarder and the `chat send` spool
+        self.start_chat_task();
+        
+        // Start running commands from deployments
+        self.start_command_task();
+        
+        // Show the end user when a technician's input reaches the machine
+        self.start_input_indicator_task();
+        
+        // Let the tray ask about sessions and pause remote access
+        self.start_control_task();
+        
+        // Connect to server
+        self.connect_to_server().await?;
+        
+        // Reaching the relay confirms a freshly installed version
+        if let Some(updater) = &self.updater {
+            if let Err(e) = updater.confirm() {
+                warn!("Failed to confirm update: {:#}", e);
+            }
+        }
+        
+        // Report inventory now and daily
+        self.start_inventory_task();
+        
+        // Upload crash reports of this and earlier runs
+        self.start_crash_report_task();
+        
+        // Start checking for releases
+        self.start_update_task();
+        
+        // Start main event loop
+        self.run_event_loop().await
+    }
+
+    /// Connect to the AtlasConnect server
+    async fn connect_to_server(&mut self) -> Result<()> {
+        info!("Connecting to server: {}", self.config.server_url);
+        
+        let capabilities = capabilities::detect(&self.config, capabilities::AgentFeatures {
+            clipboard: self.clipboard.is_some(),
+            elevation: self.elevation.is_some(),
+        }).await;
+        
+        // Network trouble is waited out; a rejected agent or a bad URL is not
+        let mut connection = loop {
+            match RelayConnection::new(&self.config, capabilities.clone()).await {
+                Ok(connection) => break connection,
+                Err(e) if e.is_retryable() => {
+                    warn!("Failed to connect to server, retrying in {}s: {}", self.config.reconnect_interval, e);
+                    tokio::time::sleep(Duration::from_secs(self.config.reconnect_interval)).await;
+                }
+                Err(e) => return Err(e.context("Failed to create relay connection")),
+            }
+        };
+        self.server_rx = connection.take_message_receiver();
+        
+        let mut relay_lock = self.relay_connection.write().await;
+        *relay_lock = Some(connection);
+        drop(relay_lock);
+        
+        info!("Successfully connected to server");
+        
+        // Pick up transfers a previous connection left unfinished
+        self.file_transfers.resume_all().await
+    }
+
+    /// Start the heartbeat task to maintain server connection
+    async fn start_heartbeat_task(&self) -> Result<()> {
+        let connection = Arc::clone(&self.relay_connection);
+        let session_manager = Arc::clone(&self.session_manager);
+        let agent_id = self.config.agent_id.clone();
+        let mut schedule = HeartbeatSchedule::new(self.policy.subscribe(), self.live.clone());
+        let updater = self.updater.clone();
+        
+        tokio::spawn(async move {
+            let mut metrics = MetricsCollector::new();
+            
+            loop {
+                schedule.tick().await;
+                
+                let active_sessions = session_manager.list_sessions().await.len() as u32;
+                let mut heartbeat = metrics.heartbeat(agent_id.clone(), active_sessions);
+                heartbeat.update = updater.as_ref().and_then(|updater| updater.report());
+                heartbeat.crashed = crash::crashed();
+                let crashed = heartbeat.crashed;
+                
+                let conn_guard = connection.read().await;
+                if let Some(conn) = conn_guard.as_ref() {
+                    match conn.send_heartbeat(heartbeat).await {
+                        Ok(()) if crashed => crash::clear_crashed(),
+                        Ok(()) => {}
+                        Err(e) => {
+                            error!("Failed to send heartbeat: {}", e);
+                            // TODO: Trigger reconnection
+                        }
+                    }
+                }
+            }
+        });
+        
+        Ok(())
+    }
+
+    /// Report inventory on registration and every `inventory.interval_secs`
+    /// after, while the local config and the server's policy allow it
+    fn start_inventory_task(&self) {
+        let connection = Arc::clone(&self.relay_connection);
+        let mut policy = self.policy.subscribe();
+        let local = self.config.inventory.clone();
+        let period = Duration::from_secs(local.interval_secs.max(60));
+
+        tokio::spawn(async move {
+            let mut interval = interval(period);
+            let mut enabled = policy.borrow_and_update().inventory_enabled.unwrap_or(local.enabled);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    Ok(()) = policy.changed() => {
+                        // Turning reporting on sends a report right away
+                        let was_enabled = enabled;
+                        enabled = policy.borrow_and_update().inventory_enabled.unwrap_or(local.enabled);
+                        if was_enabled || !enabled {
+                            continue;
+                        }
+                    }
+                }
+                if !enabled {
+                    continue;
+                }
+
+                let report = inventory::collect().await;
+                interval.reset();
+                let conn_guard = connection.read().await;
+                if let Some(conn) = conn_guard.as_ref() {
+                    if let Err(e) = conn.send_message(RelayMessage::Inventory { inventory: report }).await {
+                        warn!("Failed to send inventory: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Upload crash reports not sent yet, now and every
+    /// [`crash::UPLOAD_INTERVAL`], while the local config and the server's
+    /// policy allow it
+    fn start_crash_report_task(&self) {
+        let config = self.config.clone();
+        let policy = self.policy.subscribe();
+        let dir = config.logging.log_dir();
+
+        tokio::spawn(async move {
+            let client = crate::connection::proxy::http_client();
+            let mut interval = interval(crash::UPLOAD_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                let enabled = policy.borrow().crash_reports_enabled.unwrap_or(config.crash_reports.upload);
+                if !enabled || config.credential.is_none() {
+                    continue;
+                }
+                match crash::upload_pending(&dir, &config.agent_id, |body| crash::upload(&config, &client, body)).await {
+                    Ok(0) => {}
+                    Ok(sent) => info!("Uploaded {} crash reports", sent),
+                    Err(e) => warn!("Failed to upload crash reports: {:#}", e),
+                }
+            }
+        });
+    }
+
+    /// Keep the remote input indicator up while any session injects input,
+    /// taking it down within a poll of the last one stopping
+    fn start_input_indicator_task(&self) {
+        let session_manager = Arc::clone(&self.session_manager);
+        let mut policy = self.policy.subscribe();
+        let local = self.config.input_indicator.clone();
+
+        tokio::spawn(async move {
+            let mut interval = interval(input_indicator::POLL_INTERVAL);
+            let mut lifecycle = IndicatorLifecycle::new(input_indicator::platform_window());
+
+            loop {
+                interval.tick().await;
+                let mut settings = local.clone();
+                if policy.borrow_and_update().input_indicator_required == Some(true) {
+                    settings.require();
+                }
+
+                let mut sessions = Vec::new();
+                for session_id in session_manager.list_sessions().await {
+                    let Some(session) = session_manager.get_session(&session_id).await else {
+                        continue;
+                    };
+                    sessions.push(ControlledSession {
+                        session_type: session.session_type,
+                        technician: session.technician(),
+                        injects_input: session_manager.may_send_input(&session_id).await && session.injects_input().await,
+                    });
+                }
+                lifecycle.update(input_indicator::label(&sessions, &settings), Instant::now());
+            }
+        });
+    }
+
+    /// Serve the control API for the tray, if this agent has one
+    fn start_control_task(&self) {
+        let Some(endpoint) = self.control_endpoint.clone() else {
+            return;
+        };
+        let commands = self.control_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::server::serve(&endpoint, commands).await {
+                warn!("Control API unavailable, the tray cannot reach this agent: {:#}", e);
+            }
+        });
+    }
+
+    /// Check for new releases on the configured interval.
+    ///
+    /// Installing restarts the agent, so the check waits until no session
+    /// is active.
+    fn start_update_task(&self) {
+        let Some(updater) = self.updater.clone() else {
+            return;
+        };
+        let session_manager = Arc::clone(&self.session_manager);
+        let check_interval = Duration::from_secs(self.config.update.check_interval_secs.max(60));
+
+        tokio::spawn(async move {
+            let mut interval = interval(check_interval);
+
+            loop {
+                interval.tick().await;
+
+                if !session_manager.list_sessions().await.is_empty() {
+                    debug!("Deferring update check while sessions are active");
+                    continue;
+                }
+                match updater.run_once().await {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) => warn!("{:#}", e),
+                }
+            }
+        });
+    }
+
+    /// Probe every session's link, and report its quality every few rounds.
+    ///
+    /// The measurements also steer the session's adaptive bitrate.
+    fn start_quality_task(&self) {
+        let connection = Arc::clone(&self.relay_connection);
+        let session_manager = Arc::clone(&self.session_manager);
+        let transports = Arc::clone(&self.transports);
+        let probes = Arc::clone(&self.probes);
+        let frame_compression = Arc::clone(&self.frame_compression);
+
+        tokio::spawn(async move {
+            let mut interval = interval(PROBE_INTERVAL);
+            let mut round = 0u32;
+
+            loop {
+                interval.tick().await;
+                round = round.wrapping_add(1);
+
+                let sessions = session_manager.list_sessions().await;
+                probes.lock().retain(|session_id, _| sessions.contains(session_id));
+                frame_compression.lock().retain(|session_id| sessions.contains(session_id));
+
+                let conn_guard = connection.read().await;
+                let Some(conn) = conn_guard.as_ref() else {
+                    continue;
+                };
+                conn.retain_frame_compression(&sessions);
+
+                for session_id in sessions {
+                    let sequence = probes.lock().entry(session_id.clone()).or_default().next_probe(Instant::now());
+                    let probe = RelayMessage::LatencyProbe {
+                        session_id: session_id.clone(),
+                        sequence,
+                        timestamp: chrono::Utc::now().timestamp_millis(),
+                        echo: false,
+                    };
+                    if let Err(e) = conn.send_message(probe).await {
+                        debug!("Failed to send latency probe for session {}: {}", session_id, e);
+                        continue;
+                    }
+
+                    if !round.is_multiple_of(PROBES_PER_REPORT) {
+                        continue;
+                    }
+                    let Some(link) = probes.lock().get_mut(&session_id).map(|tracker| tracker.quality(Instant::now())) else {
+                        continue;
+                    };
+                    let Some(session) = session_manager.get_session(&session_id).await else {
+                        continue;
+                    };
+                    if link.samples > 0 {
+                        session.record_link_quality(link.rtt_ms, link.loss_percent).await;
+                    }
+                    let connection_type = match transports.read().await.get(&session_id) {
+                        Some(transport) => transport.connection_type().await,
+                        None => ConnectionType::Relay,
+                    };
+
+                    let capture = session.capture_stats().await;
+                    // Compress frames only while the link, not the CPU, holds them back
+                    if frame_compression.lock().contains(&session_id) {
+                        let active = capture.as_ref().is_some_and(|capture| {
+                            compression::should_compress_frames(capture.throughput_kbps, capture.bitrate_kbps, capture.frame_time_ms, capture.fps)
+                        });
+                        conn.set_frame_compression(&session_id, active);
+                    }
+
+                    let mut report = QualityReport::new(
+                        link,
+                        capture.as_ref(),
+                        conn.queue_stats().await.as_ref(),
+                        connection_type,
+                    );
+                    report.recovering = session.recovering();
+                    report.input = session.input_stats().await;
+                    report.compression = conn.compression_report(&session_id);
+                    if let Err(e) = conn.send_message(RelayMessage::QualityReport { session_id: session_id.clone(), report }).await {
+                        debug!("Failed to send quality report for session {}: {}", session_id, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Watch the local clipboard and send changes to sessions that share it
+    fn start_clipboard_task(&self) {
+        let Some(clipboard) = self.clipboard.clone() else {
+            return;
+        };
+        let enabled = Arc::clone(&self.clipboard_enabled);
+        let connection = Arc::clone(&self.relay_connection);
+        let session_manager = Arc::clone(&self.session_manager);
+
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_millis(500));
+
+            loop {
+                interval.tick().await;
+
+                if !enabled.load(Ordering::Relaxed) {
+                    clipboard.reset().await;
+                    continue;
+                }
+
+                let mut targets = Vec::new();
+                for session_id in session_manager.list_sessions().await {
+                    if let Some(session) = session_manager.get_session(&session_id).await {
+                        if session.shares_local_clipboard().await {
+                            targets.push(session_id);
+                        }
+                    }
+                }
+                if targets.is_empty() {
+                    clipboard.reset().await;
+                    continue;
+                }
+
+                let content = match clipboard.poll_local_change().await {
+                    Ok(Some(content)) => content,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!("Failed to read clipboard: {}", e);
+                        continue;
+                    }
+                };
+
+                let conn_guard = connection.read().await;
+                if let Some(conn) = conn_guard.as_ref() {
+                    for session_id in &targets {
+                        if let Err(e) = conn.send_message(content.to_message(session_id)).await {
+                            error!("Failed to send clipboard to session {}: {}", session_id, e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Send messages queued by file transfers over the relay connection
+    fn start_transfer_task(&mut self) {
+        let Some(mut transfer_rx) = self.transfer_rx.take() else {
+            return;
+        };
+        let connection = Arc::clone(&self.relay_connection);
+
+        tokio::spawn(async move {
+            while let Some(message) = transfer_rx.recv().await {
+                let conn_guard = connection.read().await;
+                match conn_guard.as_ref() {
+                    Some(conn) => {
+                        if let Err(e) = conn.send_message(message).await {
+                            error!("Failed to send file transfer message: {}", e);
+                        }
+                    }
+                    None => warn!("Not connected, dropping file transfer message"),
+                }
+            }
+        });
+
+        // Tell the user where files dropped by the technician were saved
+        if !self.config.file_transfer.notify_drops {
+            return;
+        }
+        let mut progress = self.file_transfers.subscribe();
+        let banners = Arc::clone(&self.banners);
+        tokio::spawn(async move {
+            loop {
+                match progress.recv().await {
+                    Ok(update) => {
+                        if let Some(banner) = drop_notice(&update) {
+                            banners.notify(banner);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Run deployment commands one at a time and send their results until
+    /// the server accepts them
+    fn start_command_task(&self) {
+        let queue = Arc::clone(&self.command_queue);
+        let ready = Arc::clone(&self.commands_ready);
+        let connection = Arc::clone(&self.relay_connection);
+        let policy = self.policy.subscribe();
+        let configured = self.config.toolbox.clone();
+
+        tokio::spawn(async move {
+            let mut retry = interval(COMMAND_RETRY);
+
+            loop {
+                tokio::select! {
+                    _ = ready.notified() => {}
+                    _ = retry.tick() => {}
+                }
+
+                while let Some(job) = queue.start_next() {
+                    let mut toolbox = configured.clone();
+                    policy.borrow().apply_toolbox(&mut toolbox);
+                    let result = queue::execute(&job, toolbox).await;
+                    info!("Execution {} for deployment {}: {:?}", job.execution_id, job.deployment_id, result.status);
+                    queue.finish(job.execution_id, result);
+                }
+
+                let conn_guard = connection.read().await;
+                let Some(conn) = conn_guard.as_ref() else {
+                    continue;
+                };
+                for (execution_id, deployment_id, result) in queue.unreported() {
+                    if let Err(e) = conn.send_message(RelayMessage::ToolResult { execution_id, deployment_id, result }).await {
+                        debug!("Failed to send result of execution {}: {}", execution_id, e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Send terminal output over the relay connection and close idle terminals
+    fn start_terminal_task(&mut self) {
+        let Some(mut terminal_rx) = self.terminal_rx.take() else {
+            return;
+        };
+        let connection = Arc::clone(&self.relay_connection);
+        let terminals = Arc::clone(&self.terminals);
+
+        tokio::spawn(async move {
+            let mut idle_check = interval(TERMINAL_IDLE_CHECK);
+            loop {
+                tokio::select! {
+                    message = terminal_rx.recv() => {
+                        let Some(message) = message else {
+                            break;
+                        };
+                        let conn_guard = connection.read().await;
+                        match conn_guard.as_ref() {
+                            Some(conn) => {
+                                if let Err(e) = conn.send_message(message).await {
+                                    error!("Failed to send terminal message: {}", e);
+                                }
+                            }
+                            None => debug!("Not connected, dropping terminal message"),
+                        }
+                    }
+                    _ = idle_check.tick() => {
+                        terminals.close_idle();
+                    }
+                }
+            }
+        });
+    }
+
+    /// Send chat messages and receipts over the relay connection, and replies
+    /// left by `chat send`
+    fn start_chat_task(&mut self) {
+        let Some(mut chat_rx) = self.chat_rx.take() else {
+            return;
+        };
+        let connection = Arc::clone(&self.relay_connection);
+        let session_manager = Arc::clone(&self.session_manager);
+        let chat = Arc::clone(&self.chat);
+        let spool = ChatSpool::default_location();
+
+        tokio::spawn(async move {
+            let mut spool_poll = interval(CHAT_SPOOL_POLL);
+            loop {
+                tokio::select! {
+                    message = chat_rx.recv() => {
+                        let Some(message) = message else {
+                            break;
+                        };
+                        let conn_guard = connection.read().await;
+                        match conn_guard.as_ref() {
+                            Some(conn) => {
+                                if let Err(e) = conn.send_message(message).await {
+                                    error!("Failed to send chat message: {}", e);
+                                }
+                            }
+                            None => warn!("Not connected, dropping chat message"),
+                        }
+                    }
+                    _ = spool_poll.tick() => {
+                        for spooled in spool.take() {
+                            // Without a session named, the reply goes to the only one
+                            let session_id = match spooled.session_id {
+                                Some(session_id) => session_id,
+                                None => match session_manager.list_sessions().await.as_slice() {
+                                    [session_id] => session_id.clone(),
+                                    [] => {
+                                        warn!("Dropping chat reply: no session is active");
+                                        continue;
+                                    }
+                                    _ => {
+                                        warn!("Dropping chat reply: several sessions are active, name one with --session");
+                                        continue;
+                                    }
+                                },
+                            };
+                            if let Err(e) = chat.send(&session_id, &spooled.body).await {
+                                warn!("Failed to send chat reply to session {}: {:#}", session_id, e);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Main event loop for processing agent messages
+    async fn run_event_loop(&mut self) -> Result<()> {
+        info!("Agent event loop started");
+        
+        let mut server_rx = self.server_rx.take();
+        let mut banner_rx = self.banner_rx.take();
+        let mut control_rx = self.control_rx.take();
+        let mut idle_check = interval(SESSION_IDLE_CHECK);
+        let mut permission_check = interval(permissions::RECHECK_INTERVAL);
+        let mut live_rx = self.live.clone();
+        loop {
+            tokio::select! {
+                // Handle shutdown signal
+                _ = self.shutdown_rx.recv() => {
+                    info!("Received shutdown signal");
+                    break;
+                }
+                
+                _ = idle_check.tick() => {
+                    self.end_idle_sessions().await;
+                    self.end_failed_sessions().await;
+                }
+                
+                // The first check prompts for whatever is missing
+                _ = permission_check.tick() => {
+                    self.end_sessions_without_permission().await;
+                }
+                
+                // The config file changed; the heartbeat follows on its own
+                Ok(()) = live_rx.changed() => {
+                    self.retune_sessions(&self.effective_config()).await;
+                }
+                
+                // Handle server messages
+                message = Self::recv_server_message(&mut server_rx) => {
+                    match message {
+                        Some(message) => {
+                            if let Err(e) = self.handle_server_message(message).await {
+                                error!("Failed to handle server message: {}", e);
+                            }
+                        }
+                        None => {
+                            warn!("Server message channel closed");
+                            server_rx = None;
+                        }
+                    }
+                }
+                
+                // The end user answered a session banner
+                Some(decision) = Self::recv_banner_decision(&mut banner_rx) => {
+                    if let Err(e) = self.handle_banner_decision(decision).await {
+                        error!("Failed to act on banner decision: {}", e);
+                    }
+                }
+                
+                // The end user asked something of the agent from the tray
+                Some(command) = Self::recv_control_command(&mut control_rx) => {
+                    self.handle_control(command).await;
+                }
+            }
+        }
+        
+        self.cleanup(&mut server_rx).await?;
+        Ok(())
+    }
+
+    /// Wait for the next server message, or forever if there is no connection
+    async fn recv_server_message(
+        server_rx: &mut Option<mpsc::Receiver<RelayMessage>>,
+    ) -> Option<RelayMessage> {
+        match server_rx {
+            Some(rx) => rx.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Wait for the next banner answer, or forever if the gate is gone
+    async fn recv_banner_decision(
+        banner_rx: &mut Option<mpsc::Receiver<BannerDecision>>,
+    ) -> Option<BannerDecision> {
+        match banner_rx {
+            Some(rx) => rx.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Wait for the next request from the tray, or forever if there is none
+    async fn recv_control_command(
+        control_rx: &mut Option<mpsc::Receiver<ControlCommand>>,
+    ) -> Option<ControlCommand> {
+        match control_rx {
+            Some(rx) => rx.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Answer a request from the tray. A join is answered once the relay
+    /// has taken or refused the code.
+    async fn handle_control(&self, ControlCommand { request, reply }: ControlCommand) {
+        let response = match request {
+            ControlRequest::Status => ControlResponse::Status { status: self.control_status().await },
+            ControlRequest::Disconnect { session_id } => self.disconnect_for_user(&session_id).await,
+            ControlRequest::Pause { hours } => self.pause_access(hours).await,
+            ControlRequest::Resume => {
+                let resumed = self.pause.lock().resume(chrono::Utc::now());
+                if resumed {
+                    info!("Remote access resumed by the user");
+                    if let Err(e) = self.send_to_server(RelayMessage::AccessPaused { until: None }).await {
+                        warn!("Failed to tell the relay access resumed: {}", e);
+                    }
+                }
+                ControlResponse::Done
+            }
+            ControlRequest::Join { code } => match self.join_support_code(&code).await {
+                Ok(code) => {
+                    *self.pending_join.lock() = Some((code, reply));
+                    return;
+                }
+                Err(response) => response,
+            },
+        };
+        let _ = reply.send(response);
+    }
+
+    /// What the tray shows: the relay connection, the running sessions
+    /// and the pause
+    async fn control_status(&self) -> AgentStatus {
+        let policy = self.policy.borrow().clone();
+        let mut sessions = Vec::new();
+        for session_id in self.session_manager.list_sessions().await {
+            let Some(session) = self.session_manager.get_session(&session_id).await else {
+                continue;
+            };
+            sessions.push(SessionSummary {
+                session_id,
+                session_type: session.session_type,
+                technician: session.technician(),
+                started_at: session.started_at(),
+                may_disconnect: policy.allows_user_disconnect(session.session_type),
+            });
+        }
+        sessions.sort_by_key(|session| session.started_at);
+        
+        let connected = match self.relay_connection.read().await.as_ref() {
+            Some(connection) => connection.is_healthy().await,
+            None => false,
+        };
+        let paused_until = self.pause.lock().until(chrono::Utc::now());
+        AgentStatus {
+            connected,
+            hostname: self.config.hostname.clone(),
+            sessions,
+            paused_until,
+            max_pause_hours: policy.user_pause_limit(),
+        }
+    }
+
+    /// End a session the end user asked to end, if policy lets them
+    async fn disconnect_for_user(&self, session_id: &str) -> ControlResponse {
+        let Some(session) = self.session_manager.get_session(session_id).await else {
+            return ControlResponse::error(format!("Session {} not found", session_id));
+        };
+        if !self.policy.borrow().allows_user_disconnect(session.session_type) {
+            return ControlResponse::error(format!("Your organization does not allow ending {} sessions", session.session_type));
+        }
+        
+        info!("Session {} ended by the user", session_id);
+        self.end_for_user(session_id, "Ended by the user").await;
+        ControlResponse::Done
+    }
+
+    /// Pause remote access for `hours`, ending every running session
+    async fn pause_access(&self, hours: u32) -> ControlResponse {
+        let limit = self.policy.borrow().user_pause_limit();
+        let until = match self.pause.lock().pause(hours, limit, chrono::Utc::now()) {
+            Ok(until) => until,
+            Err(refused) => return ControlResponse::error(refused.to_string()),
+        };
+        
+        info!("Remote access paused by the user until {}", until);
+        for session_id in self.session_manager.list_sessions().await {
+            self.end_for_user(&session_id, "Remote access paused by the user").await;
+        }
+        if let Err(e) = self.send_to_server(RelayMessage::AccessPaused { until: Some(until) }).await {
+            warn!("Failed to tell the relay access is paused: {}", e);
+        }
+        ControlResponse::Done
+    }
+
+    /// End a session on the end user's behalf, telling the server `reason`
+    async fn end_for_user(&self, session_id: &str, reason: &str) {
+        let end = RelayMessage::SessionEnd { session_id: session_id.to_string(), reason: Some(reason.to_string()), error_code: None };
+        if let Err(e) = self.send_to_server(end).await {
+            warn!("Failed to end session {} with server: {}", session_id, e);
+        }
+        if let Err(e) = self.stop_session(session_id).await {
+            error!("Failed to stop session {}: {}", session_id, e);
+        }
+    }
+
+    /// Ask the relay to let a technician in with `code`; returns the code
+    /// as sent, or the answer for the tray when it cannot be
+    async fn join_support_code(&self, code: &str) -> std::result::Result<String, ControlResponse> {
+        let code: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_uppercase();
+        if code.is_empty() {
+            return Err(ControlResponse::error("Enter the code your technician gave you"));
+        }
+        if self.config.support_code.is_some() {
+            return Err(ControlResponse::error("This agent already runs for a support code"));
+        }
+        let paused_until = self.pause.lock().until(chrono::Utc::now());
+        if let Some(until) = paused_until {
+            return Err(ControlResponse::error(format!("Remote access is paused until {}; resume it first", until)));
+        }
+        let joining = self.pending_join.lock().is_some();
+        if joining {
+            return Err(ControlResponse::error("Already joining a support code"));
+        }
+        
+        info!("Joining support code {} for the user", code);
+        match self.send_to_server(RelayMessage::JoinSupportCode { code: code.clone() }).await {
+            Ok(()) => Ok(code),
+            Err(e) => Err(ControlResponse::error(format!("Not connected to the relay: {}", e))),
+        }
+    }
+
+    /// Answer the tray's join of `code`, if it is waiting on it
+    fn finish_join(&self, code: &str, response: ControlResponse) -> bool {
+        let mut pending = self.pending_join.lock();
+        if pending.as_ref().is_some_and(|(joining, _)| joining == code) {
+            if let Some((_, reply)) = pending.take() {
+                let _ = reply.send(response);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Start a session the end user accepted, or tell the server why it
+    /// will not start
+    async fn handle_banner_decision(&self, decision: BannerDecision) -> Result<()> {
+        let Some(HeldSession { request, consent }) = self.banners.release(&decision) else {
+            debug!("Session {} ended before its banner was answered", decision.session_id);
+            return Ok(());
+        };
+        if let Some(consent) = consent {
+            return self.finish_consent(request, consent.settle(decision.outcome)).await;
+        }
+        match decision.outcome {
+            BannerOutcome::Cancelled(reason) => {
+                info!("Cancelling session {}: {}", decision.session_id, reason);
+                self.send_to_server(RelayMessage::SessionCancelled {
+                    session_id: decision.session_id,
+                    reason,
+                }).await
+            }
+            BannerOutcome::Accepted | BannerOutcome::Shown => {
+                info!("Banner for session {} accepted", decision.session_id);
+                self.send_to_server(RelayMessage::BannerAcknowledged {
+                    session_id: decision.session_id,
+                    banner_id: decision.banner_id,
+                }).await?;
+                self.handle_server_message(request).await
+            }
+        }
+    }
+
+    /// Start a session the end user consented to, or tell the server it
+    /// was declined
+    async fn finish_consent(&self, request: RelayMessage, outcome: ConsentOutcome) -> Result<()> {
+        let RelayMessage::SessionRequest { session_id, session_type, requester, record, capabilities, bandwidth_kbps, compress_frames, .. } = request else {
+            return Ok(());
+        };
+        match outcome {
+            ConsentOutcome::Headless(_) => {
+                error!("No display to ask for consent to session {}: {}", session_id, outcome);
+            }
+            ConsentOutcome::TimedOut(_) => warn!("Session {}: {}", session_id, outcome),
+            ConsentOutcome::Accepted | ConsentOutcome::Declined => info!("Session {}: {}", session_id, outcome),
+        }
+        if !outcome.accepted() {
+            return self.send_to_server(RelayMessage::SessionResponse {
+                session_id,
+                accepted: false,
+                reason: Some(outcome.to_string()),
+                readiness: None,
+                error_code: None,
+            }).await;
+        }
+        self.start_requested_session(session_id, session_type, requester, record, capabilities, bandwidth_kbps, compress_frames, Some(outcome)).await
+    }
+
+    /// Start a requested session once nothing is left to ask the end user,
+    /// and answer the server with the result
+    #[allow(clippy::too_many_arguments)]
+    async fn start_requested_session(
+        &self,
+        session_id: String,
+        session_type: String,
+        requester: String,
+        record: Option<bool>,
+        capabilities: Option<ViewerCapabilities>,
+        bandwidth_kbps: Option<u32>,
+        compress_frames: bool,
+        consent: Option<ConsentOutcome>,
+    ) -> Result<()> {
+        let required = self.policy.borrow().recording_required();
+        let record = required || record.unwrap_or(self.config.recording.enabled);
+        let result = match session_type.parse::<SessionType>() {
+            Ok(session_type) => {
+                let recording = record.then(|| OperatorInfo::from_requester(&requester));
+                self.handle_session_request(session_type, session_id.clone(), recording, capabilities).await
+            }
+            Err(e) => Err(e),
+        };
+        
+        let (accepted, reason, readiness) = match result {
+            Ok(readiness) => (true, consent.map(|outcome| outcome.to_string()), Some(readiness)),
+            Err(e) => {
+                warn!("Rejecting session {}: {}", session_id, e);
+                let readiness = match &e {
+                    GhostLinkError::Session(SessionError::NotReady { readiness, .. }) => Some(readiness.clone()),
+                    _ => None,
+                };
+                (false, Some(e.to_string()), readiness)
+            }
+        };
+        
+        let error_code = readiness.as_ref()
+            .and_then(|readiness| readiness.first_failure())
+            .and_then(|failure| failure.code);
+        self.send_to_server(RelayMessage::SessionResponse {
+            session_id: session_id.clone(),
+            accepted,
+            reason,
+            readiness,
+            error_code,
+        }).await?;
+        
+        if accepted {
+            // The viewer learns the codec before any frame arrives
+            if let Some(session) = self.session_manager.get_session(&session_id).await {
+                session.set_technician(&requester);
+                // Held to the cap from the first frame
+                if bandwidth_kbps.is_some() {
+                    session.set_bandwidth_cap(bandwidth_kbps).await;
+                }
+                // Switched on by the quality task once the link lags
+                if compress_frames {
+                    self.frame_compression.lock().insert(session_id.clone());
+                }
+                if let Some(config) = session.stream_config().await {
+                    self.send_to_server(RelayMessage::StreamConfig { session_id: session_id.clone(), config }).await?;
+                }
+            }
+            if let Err(e) = self.start_display_events(&session_id).await {
+                warn!("Failed to announce displays for session {}: {}", session_id, e);
+            }
+            self.start_cursor_updates(&session_id).await;
+            self.start_health_events(&session_id).await;
+            self.start_input_previews(&session_id).await;
+        }
+        Ok(())
+    }
+
+    /// Dispatch a server message that requires agent-level handling
+    async fn handle_server_message(&self, message: RelayMessage) -> Result<()> {
+        match message {
+            RelayMessage::SessionRequest { session_id, session_type, requester, record, capabilities, banner, requires_consent, bandwidth_kbps, compress_frames } => {
+                info!("Session {} requested by {}", session_id, requester);
+                
+                // Nobody is asked anything while the end user has access paused
+                let paused_until = self.pause.lock().until(chrono::Utc::now());
+                if let Some(until) = paused_until {
+                    info!("Refusing session {}: access paused until {}", session_id, until);
+                    return self.send_to_server(RelayMessage::SessionResponse {
+                        session_id,
+                        accepted: false,
+                        reason: Some(format!("Remote access is paused by the user until {}", until.format("%Y-%m-%d %H:%M UTC"))),
+                        readiness: None,
+                        error_code: None,
+                    }).await;
+                }
+                
+                // Nothing is captured until the end user has accepted the banner
+                if let Some(banner) = banner {
+                    if banner.acknowledgment_required {
+                        info!("Holding session {} until its banner is accepted", session_id);
+                        let request = RelayMessage::SessionRequest {
+                            session_id, session_type, requester, record, capabilities, banner: None, requires_consent, bandwidth_kbps, compress_frames,
+                        };
+                        self.banners.hold(banner, HeldSession { request, consent: None });
+                        return Ok(());
+                    }
+                    self.banners.notify(banner);
+                }
+                
+                // ...nor before they have agreed to the session itself
+                if requires_consent {
+                    let consent = ConsentPolicy::from_policy(&self.policy.borrow());
+                    let prompt = consent.prompt(&session_id, &session_type, &requester);
+                    let request = RelayMessage::SessionRequest {
+                        session_id, session_type, requester, record, capabilities, banner: None, requires_consent, bandwidth_kbps, compress_frames,
+                    };
+                    if !self.banners.display_available() {
+                        return self.finish_consent(request, ConsentOutcome::Headless(consent.on_timeout)).await;
+                    }
+                    info!("Asking the user to consent to session {}", prompt.session_id);
+                    self.banners.hold(prompt, HeldSession { request, consent: Some(consent) });
+                    return Ok(());
+                }
+                
+                if session_type.eq_ignore_ascii_case("backstage") && self.policy.borrow().consent_required == Some(true) {
+                    info!("Backstage session {} skips the consent prompt by policy", session_id);
+                }
+                self.start_requested_session(session_id, session_type, requester, record, capabilities, bandwidth_kbps, compress_frames, None).await
+            }
+            RelayMessage::MonitorControl { session_id, data } => {
+                self.handle_monitor_control(&session_id, data).await
+            }
+            RelayMessage::SessionEnd { session_id, .. } => {
+                if self.banners.cancel(&session_id) {
+                    info!("Session {} ended while its banner was up", session_id);
+                    return Ok(());
+                }
+                self.stop_session(&session_id).await
+            }
+            RelayMessage::SessionKeepalive { session_id } => {
+                self.touch_session(&session_id).await;
+                Ok(())
+            }
+            RelayMessage::InputEvent { session_id, event_type, data } => {
+                if let Err(e) = self.session_manager.route_input_event(&session_id, event_type, data).await {
+                    warn!("Dropping input event for session {}: {}", session_id, e);
+                    self.send_to_server(RelayMessage::Error {
+                        code: 404,
+                        message: format!("Input event rejected for session {}: {}", session_id, e),
+                        session_id: Some(session_id),
+                    }).await?;
+                }
+                Ok(())
+            }
+            RelayMessage::CollectArchive { session_id, request_id, path, include_globs, max_size } => {
+                if self.session_manager.get_session(&session_id).await.is_none() {
+                    return self.send_to_server(RelayMessage::ArchiveError {
+                        request_id,
+                        message: format!("Session {} not found", session_id),
+                    }).await;
+                }
+                
+                archive::spawn_archive_upload(
+                    Arc::clone(&self.relay_connection),
+                    self.effective_config().file_transfer,
+                    request_id,
+                    ArchiveRequest {
+                        root: path.into(),
+                        include_globs,
+                        max_size,
+                    },
+                );
+                Ok(())
+            }
+            RelayMessage::RegistryRequest { session_id, request_id, operation } => {
+                let (result, audit) = if self.session_manager.get_session(&session_id).await.is_none() {
+                    (Err(SessionError::NotFound { session_id: session_id.clone() }.into()), None)
+                } else if let Some(registry) = self.registry.clone() {
+                    match tokio::task::spawn_blocking(move || registry.execute(&session_id, &operation)).await {
+                        Ok((result, audit)) => (result.map_err(GhostLinkError::from), Some(audit)),
+                        Err(e) => (Err(GhostLinkError::Other(format!("Registry task failed: {}", e))), None),
+                    }
+                } else {
+                    (Err(GhostLinkError::Other("Registry editing is only supported on Windows agents".to_string())), None)
+                };
+                
+                let message = match result {
+                    Ok(value) => RelayMessage::RegistryResult {
+                        request_id,
+                        success: true,
+                        result: Some(value),
+                        error: None,
+                        audit,
+                    },
+                    Err(e) => RelayMessage::RegistryResult {
+                        request_id,
+                        success: false,
+                        result: None,
+                        error: Some(e.to_string()),
+                        audit,
+                    },
+                };
+                self.send_to_server(message).await
+            }
+            message @ (RelayMessage::ListDirectory { .. }
+            | RelayMessage::StatEntry { .. }
+            | RelayMessage::CreateDirectory { .. }
+            | RelayMessage::Rename { .. }
+            | RelayMessage::Delete { .. }) => {
+                let Some((session_id, request_id, request)) = FsRequest::from_message(message) else {
+                    return Ok(());
+                };
+                let result = match self.session_manager.get_session(&session_id).await {
+                    None => Err(FsError::Unavailable { reason: format!("session {} not found", session_id) }),
+                    Some(session) => {
+                        // Elevated sessions browse beyond the user profiles
+                        let elevated = session.elevation().current().await.is_some();
+                        let file_browser = FileBrowser::new(&self.effective_config().file_transfer, elevated);
+                        tokio::task::spawn_blocking(move || file_browser.execute(&request))
+                            .await
+                            .unwrap_or_else(|e| Err(FsError::Unavailable { reason: format!("file browser task failed: {}", e) }))
+                    }
+                };
+                if let Err(e) = &result {
+                    debug!("File browser request {} of session {} failed: {}", request_id, session_id, e);
+                }
+                self.send_to_server(browser::result_message(&session_id, &request_id, result)).await
+            }
+            RelayMessage::ElevationRequest { session_id, level } => {
+                info!("Elevation to {} requested for session {}", level, session_id);
+                let result = match (self.session_manager.get_session(&session_id).await, self.elevation.clone()) {
+                    (None, _) => Err(ElevationError::Failed {
+                        reason: format!("Session {} not found", session_id),
+                    }),
+                    (Some(_), None) => Err(ElevationError::Unavailable {
+                        reason: "privilege elevation is not supported on this platform".to_string(),
+                    }),
+                    (Some(session), Some(backend)) => session.elevate(backend, level.clone()).await,
+                };
+                self.send_to_server(elevation::result_message(&session_id, &level, &result)).await
+            }
+            RelayMessage::SetEncoderProfile { session_id, profile } => {
+                let result = match self.session_manager.get_session(&session_id).await {
+                    Some(session) => session.set_encoder_profile(profile).await,
+                    None => Err(SessionError::NotFound { session_id: session_id.clone() }.into()),
+                };
+                if let Err(e) = result {
+                    warn!("Failed to switch encoder profile for session {}: {}", session_id, e);
+                    self.send_to_server(RelayMessage::Error {
+                        code: 400,
+                        message: format!("Encoder profile change rejected for session {}: {}", session_id, e),
+                        session_id: Some(session_id),
+                    }).await?;
+                }
+                Ok(())
+            }
+            RelayMessage::SetBandwidthCap { session_id, kbps } => {
+                match self.session_manager.get_session(&session_id).await {
+                    Some(session) => session.set_bandwidth_cap(kbps).await,
+                    None => warn!("Bandwidth cap for unknown session {}", session_id),
+                }
+                Ok(())
+            }
+            RelayMessage::SetQuality { session_id, quality } => {
+                let result = match self.session_manager.get_session(&session_id).await {
+                    Some(session) => session.set_quality(quality).await,
+                    None => Err(SessionError::NotFound { session_id: session_id.clone() }.into()),
+                };
+                if let Err(e) = result {
+                    warn!("Failed to set quality for session {}: {}", session_id, e);
+                }
+                Ok(())
+            }
+            RelayMessage::StreamDowngrade { session_id, .. } => {
+                let result = match self.session_manager.get_session(&session_id).await {
+                    Some(session) => session.downgrade_stream().await,
+                    None => Err(SessionError::NotFound { session_id: session_id.clone() }.into()),
+                };
+                match result {
+                    Ok(config) => self.send_to_server(RelayMessage::StreamConfig { session_id, config }).await,
+                    Err(e) => {
+                        // The session stays up; the viewer keeps what it has
+                        warn!("Cannot downgrade the stream of session {}: {}", session_id, e);
+                        Ok(())
+                    }
+                }
+            }
+            RelayMessage::SetCursorStreaming { session_id, enabled } => {
+                match self.session_manager.get_session(&session_id).await {
+                    Some(session) => session.set_cursor_streaming(enabled).await,
+                    None => warn!("Cursor streaming toggle for unknown session {}", session_id),
+                }
+                Ok(())
+            }
+            RelayMessage::SetInputMode { session_id, mode } => {
+                let applied = match self.session_manager.get_session(&session_id).await {
+                    Some(session) => session.set_input_mode(mode).await,
+                    None => {
+                        warn!("Input mode change for unknown session {}", session_id);
+                        return Ok(());
+                    }
+                };
+                let reason = (applied != mode).then(|| "This device cannot inject relative pointer motion".to_string());
+                self.send_to_server(RelayMessage::InputModeChanged { session_id, mode: applied, reason }).await
+            }
+            RelayMessage::SetInputPreview { session_id, enabled } => {
+                let Some(session) = self.session_manager.get_session(&session_id).await else {
+                    warn!("Input preview toggle for unknown session {}", session_id);
+                    return Ok(());
+                };
+                let enabled = session.set_input_preview(enabled).await;
+                self.send_to_server(RelayMessage::InputPreviewChanged { session_id, enabled }).await
+            }
+            RelayMessage::SessionOperatorChanged { session_id, requester } => {
+                match self.session_manager.get_session(&session_id).await {
+                    Some(session) => {
+                        info!("Session {} is now run by {}", session_id, requester);
+                        session.set_technician(&requester);
+                    }
+                    None => warn!("Operator change for unknown session {}", session_id),
+                }
+                Ok(())
+            }
+            RelayMessage::RequestKeyframe { session_id } => {
+                let result = match self.session_manager.get_session(&session_id).await {
+                    Some(session) => session.request_keyframe().await,
+                    None => Err(SessionError::NotFound { session_id: session_id.clone() }.into()),
+                };
+                if let Err(e) = result {
+                    debug!("Failed to request a keyframe for session {}: {}", session_id, e);
+                }
+                Ok(())
+            }
+            RelayMessage::ClipboardSync { session_id, content, content_type } => {
+                let Some(clipboard) = self.clipboard.as_ref().filter(|_| self.clipboard_enabled.load(Ordering::Relaxed)) else {
+                    debug!("Clipboard sync disabled, ignoring update for session {}", session_id);
+                    return Ok(());
+                };
+                match self.session_manager.get_session(&session_id).await {
+                    Some(session) if session.clipboard_sync_enabled().await => {
+                        if let Err(e) = clipboard.apply_remote(&content, &content_type).await {
+                            warn!("Failed to apply clipboard for session {}: {}", session_id, e);
+                        }
+                    }
+                    Some(_) => debug!("Clipboard sync off for session {}", session_id),
+                    None => warn!("Clipboard update for unknown session {}", session_id),
+                }
+                Ok(())
+            }
+            RelayMessage::LatencyProbe { session_id, sequence, timestamp, echo } => {
+                if echo {
+                    if let Some(tracker) = self.probes.lock().get_mut(&session_id) {
+                        tracker.record_echo(sequence, Instant::now());
+                    }
+                    return Ok(());
+                }
+                self.send_to_server(RelayMessage::LatencyProbe { session_id, sequence, timestamp, echo: true }).await
+            }
+            RelayMessage::WakeOnLan { mac, broadcast } => {
+                if let Err(e) = network::send_magic_packet(&mac, broadcast).await {
+                    warn!("Failed to wake {}: {:#}", mac, e);
+                }
+                Ok(())
+            }
+            RelayMessage::P2PHandshake { session_id, connection_info } => {
+                self.handle_p2p_handshake(&session_id, connection_info).await
+            }
+            RelayMessage::P2PResponse { session_id, accepted, connection_info } => {
+                match self.transports.read().await.get(&session_id) {
+                    Some(transport) => transport.handle_p2p_response(accepted, connection_info).await,
+                    None => debug!("P2P response for session {} without a transport", session_id),
+                }
+                Ok(())
+            }
+            RelayMessage::UdpLaneOffer { session_id, port, token } => {
+                self.open_udp_lane(&session_id, port, &token).await
+            }
+            RelayMessage::UdpLaneClosed { session_id, reason } => {
+                if let Some(transport) = self.transports.read().await.get(&session_id) {
+                    transport.close_udp_lane(reason.as_deref().unwrap_or("closed by the relay")).await;
+                }
+                Ok(())
+            }
+            message @ (RelayMessage::TerminalOpen { .. }
+            | RelayMessage::TerminalInput { .. }
+            | RelayMessage::TerminalResize { .. }
+            | RelayMessage::TerminalClose { .. }
+            | RelayMessage::TerminalScrollbackRequest { .. }) => {
+                self.terminals.handle_message(message).await.map_err(GhostLinkError::from)
+            }
+            message @ (RelayMessage::ChatMessage { .. }
+            | RelayMessage::ChatReceipt { .. }
+            | RelayMessage::ChatError { .. }) => {
+                // Chat from the technician counts as activity
+                if let RelayMessage::ChatMessage { ref session_id, .. } = message {
+                    self.touch_session(session_id).await;
+                }
+                self.chat.handle_message(message).await.map_err(GhostLinkError::from)
+            }
+            RelayMessage::SupportCodeAccepted { code, expires_at } => {
+                let minutes = (expires_at - chrono::Utc::now()).num_minutes().max(0);
+                info!("Support code {} accepted; waiting up to {} minutes for the technician", code, minutes);
+                self.finish_join(&code, ControlResponse::Joined { code: code.clone(), expires_at });
+                Ok(())
+            }
+            RelayMessage::SupportCodeClosed { code, reason } => {
+                // An enrolled agent stays up once a code it joined is done
+                if self.finish_join(&code, ControlResponse::error(reason.clone())) {
+                    info!("Support code {} refused: {}", code, reason);
+                } else if self.config.support_code.is_some() {
+                    info!("Support code closed ({}), shutting down", reason);
+                    // Full means a shutdown is already on its way
+                    let _ = self.shutdown_tx.try_send(());
+                }
+                Ok(())
+            }
+            RelayMessage::ControlChanged { holder } => {
+                self.session_manager.set_control_holder(holder).await;
+                Ok(())
+            }
+            RelayMessage::PolicyUpdate { policy } => {
+                self.apply_policy(policy).await;
+                Ok(())
+            }
+            RelayMessage::ExecuteTool { job } => {
+                let execution_id = job.execution_id;
+                if !self.command_queue.enqueue(job) {
+                    debug!("Execution {} is already queued", execution_id);
+                }
+                // Runs new commands, and resends the result of one the server missed
+                self.commands_ready.notify_one();
+                Ok(())
+            }
+            RelayMessage::ToolResultAccepted { execution_id } => {
+                self.command_queue.acknowledge(execution_id);
+                Ok(())
+            }
+            message @ (RelayMessage::FileTransferStart { .. }
+            | RelayMessage::FileTransfer { .. }
+            | RelayMessage::FileTransferResume { .. }
+            | RelayMessage::FileTransferComplete { .. }
+            | RelayMessage::FileTransferCancel { .. }
+            | RelayMessage::FileTransferRequest { .. }) => {
+                self.file_transfers.handle_message(message).await
+            }
+            other => {
+                debug!("Unhandled server message: {:?}", other);
+                Ok(())
+            }
+        }
+    }
+
+    /// Restart a session's idle timeout
+    async fn touch_session(&self, session_id: &str) {
+        if let Some(session) = self.session_manager.get_session(session_id).await {
+            session.touch().await;
+        }
+    }
+
+    /// End sessions that outlived their idle timeout, telling the server
+    /// first so the technician sees why
+    async fn end_idle_sessions(&self) {
+        for session_id in self.session_manager.list_sessions().await {
+            let Some(session) = self.session_manager.get_session(&session_id).await else {
+                continue;
+            };
+            if !session.idle_expired().await {
+                continue;
+            }
+            
+            info!("Ending idle session {}", session_id);
+            if let Err(e) = self.send_to_server(RelayMessage::SessionEnd { session_id: session_id.clone(), reason: None, error_code: None }).await {
+                warn!("Failed to end idle session {} with server: {}", session_id, e);
+            }
+            if let Err(e) = self.stop_session(&session_id).await {
+                error!("Failed to stop idle session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// End sessions the watchdog could not recover, with its reason, so
+    /// the technician sees why instead of a frozen screen
+    async fn end_failed_sessions(&self) {
+        for session_id in self.session_manager.list_sessions().await {
+            let Some(session) = self.session_manager.get_session(&session_id).await else {
+                continue;
+            };
+            let Some(reason) = session.failure() else {
+                continue;
+            };
+            
+            warn!("Ending session {}: {}", session_id, reason);
+            let end = RelayMessage::SessionEnd { session_id: session_id.clone(), reason: Some(reason), error_code: None };
+            if let Err(e) = self.send_to_server(end).await {
+                warn!("Failed to end session {} with server: {}", session_id, e);
+            }
+            if let Err(e) = self.stop_session(&session_id).await {
+                error!("Failed to stop session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// Check the macOS permissions again and end every session once one
+    /// is taken away: capture would go black, input would go nowhere
+    async fn end_sessions_without_permission(&self) {
+        let Some((permission, _)) = permissions::recheck().into_iter().find(|(_, state)| *state == PermissionState::Revoked) else {
+            return;
+        };
+        let reason = PreflightError::PermissionMissing {
+            permission: permission.to_string(),
+            remediation: permission.remediation(),
+        }.to_string();
+        
+        for session_id in self.session_manager.list_sessions().await {
+            warn!("Ending session {}: {}", session_id, reason);
+            let end = RelayMessage::SessionEnd {
+                session_id: session_id.clone(),
+                reason: Some(reason.clone()),
+                error_code: Some(FailureCode::PermissionMissing),
+            };
+            if let Err(e) = self.send_to_server(end).await {
+                warn!("Failed to end session {} with server: {}", session_id, e);
+            }
+            if let Err(e) = self.stop_session(&session_id).await {
+                error!("Failed to stop session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// Send a message to the server over the relay connection
+    async fn send_to_server(&self, message: RelayMessage) -> Result<()> {
+        let relay_lock = self.relay_connection.read().await;
+        match relay_lock.as_ref() {
+            Some(connection) => connection.send_message(message).await,
+            None => Err(ConnectionError::NotConnected.into()),
+        }
+    }
+
+    /// Handle incoming session request from server
+    pub async fn handle_session_request(
+        &self,
+        session_type: SessionType,
+        session_id: String,
+        recording: Option<OperatorInfo>,
+        capabilities: Option<ViewerCapabilities>,
+    ) -> Result<SessionReadiness> {
+        info!("Received session request: {} ({})", session_id, session_type);
         
-        // Start heartbeat task
-        self.start_heartbeat_task().await?;
+        if !self.policy.borrow().allows_session(session_type) {
+            return Err(GhostLinkError::Other(format!("{} sessions are not allowed by policy", session_type)));
+        }
+        if !self.session_manager.can_accept_session(session_type).await {
+            return Err(GhostLinkError::Other(format!("Session limit reached for {} sessions", session_type)));
+        }
         
-        // Connect to server
-        self.connect_to_server().await?;
+        // Create new session; nothing is reported active before its pre-flight passes
+        let (session, readiness) = self.sessions.open(session_id.clone(), session_type, &self.effective_config(), &self.relay_connection)
+            .await
+            .map_err(|readiness| SessionError::NotReady {
+                reason: readiness.first_failure()
+                    .map(|failure| failure.diagnostic.clone())
+                    .unwrap_or_else(|| "Session pre-flight failed".to_string()),
+                readiness,
+            })?;
         
-        // Start main event loop
-        self.run_event_loop().await
-    }
-
-    /// Connect to the AtlasConnect server
-    async fn connect_to_server(&self) -> Result<()> {
-        info!("Connecting to server: {}", self.config.server_url);
+        // Without a codec the viewer decodes there is nothing to show it
+        if let Some(capabilities) = capabilities {
+            if let Err(e) = session.negotiate_stream(capabilities).await {
+                let _ = session.stop().await;
+                return Err(e.context("Failed to negotiate a codec with the viewer"));
+            }
+        }
         
-        let connection = RelayConnection::new(&self.config).await
-            .context("Failed to create relay connection")?;
+        // A session that must be recorded does not start unrecorded
+        if let Some(operator) = recording {
+            if let Err(e) = session.start_recording(operator).await {
+                let _ = session.stop().await;
+                return Err(e.context("Failed to start session recording"));
+            }
+        }
         
-        let mut relay_lock = self.relay_connection.write().await;
-        *relay_lock = Some(connection);
+        // Register with session manager
+        self.session_manager.add_session(session_id, session).await?;
         
-        info!("Successfully connected to server");
-        Ok(())
+        Ok(readiness)
     }
 
-    /// Start the heartbeat task to maintain server connection
-    async fn start_heartbeat_task(&self) -> Result<()> {
+    /// Send the viewer the session's display list and keep it informed of
+    /// display switches and hot-plug changes
+    async fn start_display_events(&self, session_id: &str) -> Result<()> {
+        let Some(session) = self.session_manager.get_session(session_id).await else {
+            return Ok(());
+        };
+        let Some(mut events) = session.subscribe_display_events().await else {
+            return Ok(());
+        };
+
+        let (monitors, current) = session.monitor_list().await?;
+        self.send_monitor_control(session_id, MonitorControlMessage::MonitorList { monitors, current }).await?;
+
         let connection = Arc::clone(&self.relay_connection);
-        let heartbeat_interval = Duration::from_secs(self.config.heartbeat_interval);
-        
+        let session_id = session_id.to_string();
         tokio::spawn(async move {
-            let mut interval = interval(heartbeat_interval);
-            
             loop {
-                interval.tick().await;
-                
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    // The session's capture was torn down
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                // The viewer resizes its decoder from the stream config
+                let resized = matches!(event, DisplayEvent::Switched { .. });
+                let mut messages = vec![monitor_control_message(&session_id, MonitorControlMessage::from_display_event(event))];
+                if resized {
+                    if let Some(config) = session.stream_config().await {
+                        messages.push(RelayMessage::StreamConfig { session_id: session_id.clone(), config });
+                    }
+                }
+
                 let conn_guard = connection.read().await;
                 if let Some(conn) = conn_guard.as_ref() {
-                    if let Err(e) = conn.send_heartbeat().await {
-                        error!("Failed to send heartbeat: {}", e);
-                        // TODO: Trigger reconnection
+                    for message in messages {
+                        if let Err(e) = conn.send_message(message).await {
+                            error!("Failed to send display change for session {}: {}", session_id, e);
+                        }
                     }
                 }
             }
         });
-        
+
         Ok(())
     }
 
-    /// Main event loop for processing agent messages
-    async fn run_event_loop(&mut self) -> Result<()> {
-        info!("Agent event loop started");
-        
-        // TODO: Set up message channel for handling server messages
-        loop {
-            tokio::select! {
-                // Handle shutdown signal
-                _ = self.shutdown_rx.recv() => {
-                    info!("Received shutdown signal");
-                    break;
+    /// Relay the session watchdog's restarts to the viewer, which shows
+    /// them over the stream while it recovers
+    async fn start_health_events(&self, session_id: &str) {
+        let Some(session) = self.session_manager.get_session(session_id).await else {
+            return;
+        };
+        let mut events = session.subscribe_health_events();
+        drop(session);
+
+        let connection = Arc::clone(&self.relay_connection);
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let conn_guard = connection.read().await;
+                if let Some(conn) = conn_guard.as_ref() {
+                    let message = RelayMessage::SessionHealth { session_id: session_id.clone(), event };
+                    if let Err(e) = conn.send_message(message).await {
+                        debug!("Failed to send health of session {}: {}", session_id, e);
+                    }
                 }
-                
-                // Handle server messages
-                // TODO: Implement server message handling
-                
-                // Keep the loop alive
-                _ = tokio::time::sleep(Duration::from_millis(100)) => {}
             }
+        });
+    }
+
+    /// Acknowledge events the session validated in preview mode to the
+    /// viewer, which shows where they would have landed
+    async fn start_input_previews(&self, session_id: &str) {
+        let Some(session) = self.session_manager.get_session(session_id).await else {
+            return;
+        };
+        let mut previews = session.subscribe_input_previews();
+        drop(session);
+
+        let connection = Arc::clone(&self.relay_connection);
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            loop {
+                let event = match previews.recv().await {
+                    Ok(event) => event,
+                    // The counters in the quality report keep the total
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let conn_guard = connection.read().await;
+                if let Some(conn) = conn_guard.as_ref() {
+                    let message = RelayMessage::InputPreviewed { session_id: session_id.clone(), event };
+                    if let Err(e) = conn.send_message(message).await {
+                        debug!("Failed to acknowledge previewed input of session {}: {}", session_id, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Relay the session's cursor updates to the viewer as they come, hinting
+    /// at relative input while the focused app holds the pointer
+    async fn start_cursor_updates(&self, session_id: &str) {
+        let Some(session) = self.session_manager.get_session(session_id).await else {
+            return;
+        };
+        let Some(mut updates) = session.subscribe_cursor_updates().await else {
+            return;
+        };
+
+        let connection = Arc::clone(&self.relay_connection);
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            let mut capture = PointerCaptureDetector::default();
+            loop {
+                let cursor = match updates.recv().await {
+                    Ok(cursor) => cursor,
+                    // Only the latest position matters
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                // The viewer decides; the agent only points out a grab
+                let hint = capture.observe(&cursor).map(|mode| RelayMessage::InputModeHint {
+                    session_id: session_id.clone(),
+                    mode,
+                    reason: match mode {
+                        InputMode::Relative => "The focused app captured the pointer".to_string(),
+                        InputMode::Absolute => "The focused app released the pointer".to_string(),
+                    },
+                });
+
+                let conn_guard = connection.read().await;
+                if let Some(conn) = conn_guard.as_ref() {
+                    let message = RelayMessage::CursorUpdate { session_id: session_id.clone(), cursor };
+                    for message in std::iter::once(message).chain(hint) {
+                        if let Err(e) = conn.send_message(message).await {
+                            debug!("Failed to send cursor update for session {}: {}", session_id, e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Apply a viewer's monitor command; switches are confirmed through the
+    /// display event stream
+    async fn handle_monitor_control(&self, session_id: &str, data: serde_json::Value) -> Result<()> {
+        let message = match serde_json::from_value::<MonitorControlMessage>(data) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Invalid monitor control message for session {}: {}", session_id, e);
+                return Ok(());
+            }
+        };
+        let Some(session) = self.session_manager.get_session(session_id).await else {
+            return self.send_to_server(RelayMessage::Error {
+                code: 404,
+                message: format!("Session {} not found", session_id),
+                session_id: Some(session_id.to_string()),
+            }).await;
+        };
+
+        let result = match message {
+            MonitorControlMessage::GetMonitors => {
+                let (monitors, current) = session.monitor_list().await?;
+                return self.send_monitor_control(session_id, MonitorControlMessage::MonitorList { monitors, current }).await;
+            }
+            MonitorControlMessage::SelectMonitor { monitor_id } => session.select_monitor(monitor_id).await,
+            MonitorControlMessage::CaptureAllMonitors { enabled } => session.capture_all_monitors(enabled).await,
+            other => Err(GhostLinkError::Other(format!("Unsupported monitor command {}", other.message_type()))),
+        };
+
+        if let Err(e) = result {
+            warn!("Monitor command failed for session {}: {}", session_id, e);
+            self.send_monitor_control(session_id, MonitorControlMessage::ControlResponse {
+                success: false,
+                error: Some(e.to_string()),
+                data: None,
+            }).await?;
         }
-        
-        self.cleanup().await?;
         Ok(())
     }
 
-    /// Handle incoming session request from server
-    pub async fn handle_session_request(
-        &self,
-        session_type: SessionType,
-        session_id: String,
-    ) -> Result<()> {
-        info!("Received session request: {} ({})", session_id, session_type);
-        
-        // Create new session
-        let session = Session::new(session_id.clone(), session_type, &self.config).await?;
-        
-        // Register with session manager
-        self.session_manager.add_session(session_id, session).await?;
-        
+    async fn send_monitor_control(&self, session_id: &str, message: MonitorControlMessage) -> Result<()> {
+        self.send_to_server(monitor_control_message(session_id, message)).await
+    }
+
+    /// Answer a viewer's request for a direct connection
+    async fn handle_p2p_handshake(&self, session_id: &str, peer: P2PConnectionInfo) -> Result<()> {
+        if self.session_manager.get_session(session_id).await.is_none() {
+            warn!("P2P handshake for unknown session {}", session_id);
+            return self.send_to_server(RelayMessage::P2PResponse {
+                session_id: session_id.to_string(),
+                accepted: false,
+                connection_info: None,
+            }).await;
+        }
+
+        let transport = self.transport(session_id).await;
+        transport.handle_p2p_handshake(peer).await
+    }
+
+    /// Take up the relay's offer of a UDP lane for a session's frames.
+    ///
+    /// Probing runs in the background; if UDP turns out to be blocked the
+    /// relay is told and frames stay on the WebSocket.
+    async fn open_udp_lane(&self, session_id: &str, port: u16, token: &str) -> Result<()> {
+        let token = udp_lane::parse_token(token)?;
+        let server_url = crate::connection::server_url(&self.config)?;
+        let host = network::url_host(&server_url).context("Server URL has no host")?;
+        let transport = self.transport(session_id).await;
+        let relay = Arc::clone(&self.relay_connection);
+        let session_id = session_id.to_string();
+
+        tokio::spawn(async move {
+            let lane: Result<UdpLane> = async {
+                let relay_addr = tokio::net::lookup_host((host.as_str(), port)).await?
+                    .next()
+                    .with_context(|| format!("{} did not resolve", host))?;
+                UdpLane::connect(relay_addr, token, LaneTimings::default()).await
+            }.await;
+            match lane {
+                Ok(lane) => transport.activate_udp_lane(lane).await,
+                Err(e) => {
+                    info!("No UDP lane for session {}, frames stay on the WebSocket: {:#}", session_id, e);
+                    let closed = RelayMessage::UdpLaneClosed { session_id: session_id.clone(), reason: Some(e.to_string()) };
+                    if let Err(e) = relay.send_message(closed).await {
+                        debug!("Failed to decline the UDP lane of session {}: {}", session_id, e);
+                    }
+                }
+            }
+        });
         Ok(())
     }
 
+    /// The session's transport, created on first use.
+    ///
+    /// Input the viewer sends over a punched channel is routed like input
+    /// arriving through the relay.
+    async fn transport(&self, session_id: &str) -> HybridConnectionManager {
+        if let Some(transport) = self.transports.read().await.get(session_id) {
+            return transport.clone();
+        }
+
+        let transport = HybridConnectionManager::new(
+            session_id.to_string(),
+            self.relay_connection.clone(),
+            ConnectionSettings::default(),
+        );
+        if let Some(mut incoming) = transport.take_incoming().await {
+            let session_manager = self.session_manager.clone();
+            tokio::spawn(async move {
+                while let Some(envelope) = incoming.recv().await {
+                    let messages = match RelayConnection::decode_envelope(&envelope) {
+                        Ok(messages) => messages,
+                        Err(e) => {
+                            warn!("Dropping direct message: {}", e);
+                            continue;
+                        }
+                    };
+                    for message in messages {
+                        if let RelayMessage::InputEvent { session_id, event_type, data } = message {
+                            if let Err(e) = session_manager.route_input_event(&session_id, event_type, data).await {
+                                warn!("Dropping direct input event for session {}: {}", session_id, e);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        // Another message for the session may have created one meanwhile
+        self.transports.write().await.entry(session_id.to_string()).or_insert(transport).clone()
+    }
+
     /// Stop a running session
     pub async fn stop_session(&self, session_id: &str) -> Result<()> {
         info!("Stopping session: {}", session_id);
         
+        self.terminals.close(session_id, "session ended");
+        self.chat.end_session(session_id);
         self.session_manager.remove_session(session_id).await?;
+        self.file_transfers.cancel_session(session_id).await;
+        if let Some(transport) = self.transports.write().await.remove(session_id) {
+            transport.disconnect().await;
+        }
         
         Ok(())
     }
@@ -186,6 +2115,9 @@ impl Agent {
     }
 
     /// Graceful shutdown
+    ///
+    /// The agent finishes the shutdown sequence before `start` returns, so
+    /// callers should keep awaiting it after calling this.
     pub async fn shutdown(&self) -> Result<()> {
         info!("Initiating agent shutdown");
         
@@ -194,14 +2126,62 @@ impl Agent {
         Ok(())
     }
 
+    /// Chat of this agent's sessions, for showing it to the end user
+    pub fn chat(&self) -> Arc<ChatManager> {
+        Arc::clone(&self.chat)
+    }
+
+    /// Handle for requesting shutdown while `start` holds the agent
+    pub fn shutdown_handle(&self) -> mpsc::Sender<()> {
+        self.shutdown_tx.clone()
+    }
+
+    /// Handle for asking what the tray asks, without going through the
+    /// control API
+    pub fn control_handle(&self) -> mpsc::Sender<ControlCommand> {
+        self.control_tx.clone()
+    }
+
+    /// Binary to restart into once `start` returns, if an update asked for it
+    pub fn pending_restart(&self) -> Option<PathBuf> {
+        if !self.restart_pending.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.updater.as_ref().map(|updater| updater.binary_path().to_path_buf())
+    }
+
     /// Cleanup resources on shutdown
-    async fn cleanup(&self) -> Result<()> {
+    ///
+    /// Ends every session with the relay, finalizes recordings, deregisters
+    /// the agent and closes the socket with a normal close frame, in that
+    /// order, so the relay never shows a departed agent as online.
+    async fn cleanup(&self, server_rx: &mut Option<mpsc::Receiver<RelayMessage>>) -> Result<()> {
         info!("Cleaning up agent resources");
         
-        // Stop all active sessions
+        let connected = self.relay_connection.read().await.is_some();
+        if connected {
+            self.end_sessions_with_relay(server_rx).await;
+        }
+        
+        // Stop all active sessions; stopping finalizes their recordings
+        self.terminals.close_all("agent shutting down");
         self.session_manager.shutdown_all().await?;
+        for (_, transport) in self.transports.write().await.drain() {
+            transport.disconnect().await;
+        }
+        self.probes.lock().clear();
         
-        // Close server connection
+        if connected {
+            let deregister = RelayMessage::AgentDeregister {
+                agent_id: self.config.agent_id.clone(),
+                reason: "shutdown".to_string(),
+            };
+            if let Err(e) = self.send_to_server(deregister).await {
+                warn!("Failed to deregister from server: {}", e);
+            }
+        }
+        
+        // Close server connection once everything queued has gone out
         let mut relay_lock = self.relay_connection.write().await;
         if let Some(connection) = relay_lock.take() {
             connection.disconnect().await?;
@@ -210,4 +2190,495 @@ impl Agent {
         info!("Agent cleanup completed");
         Ok(())
     }
+
+    /// Send SessionEnd for every active session and wait, up to
+    /// [`SESSION_END_TIMEOUT`], for the relay to echo each one back
+    async fn end_sessions_with_relay(&self, server_rx: &mut Option<mpsc::Receiver<RelayMessage>>) {
+        let mut pending = HashSet::new();
+        for session_id in self.session_manager.list_sessions().await {
+            match self.send_to_server(RelayMessage::SessionEnd { session_id: session_id.clone(), reason: None, error_code: None }).await {
+                Ok(()) => {
+                    pending.insert(session_id);
+                }
+                Err(e) => warn!("Failed to end session {} with server: {}", session_id, e),
+            }
+        }
+        
+        let deadline = tokio::time::Instant::now() + SESSION_END_TIMEOUT;
+        while !pending.is_empty() {
+            let message = match tokio::time::timeout_at(deadline, Self::recv_server_message(server_rx)).await {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    warn!("Server connection closed before sessions were acknowledged");
+                    return;
+                }
+                Err(_) => {
+                    warn!("Server did not acknowledge {} ended session(s) in time", pending.len());
+                    return;
+                }
+            };
+            
+            match message {
+                RelayMessage::SessionEnd { session_id, .. } => {
+                    debug!("Server acknowledged end of session {}", session_id);
+                    pending.remove(&session_id);
+                }
+                other => debug!("Ignoring server message during shutdown: {:?}", other),
+            }
+        }
+    }
+}
+
+/// Banner announcing a file the technician dropped, once it is saved
+fn drop_notice(update: &TransferProgress) -> Option<SessionBanner> {
+    if !update.drop || update.state != TransferState::Completed {
+        return None;
+    }
+    let saved_to = update.saved_to.as_ref()?;
+    Some(SessionBanner {
+        id: format!("drop-{}", update.transfer_id),
+        session_id: update.session_id.clone(),
+        title: "File received".to_string(),
+        message: format!("Your technician sent {}, saved to {}", update.filename, saved_to.display()),
+        company_name: "GhostLink".to_string(),
+        colors: BannerColors {
+            background: "#0d6efd".to_string(),
+            text: "#ffffff".to_string(),
+            accent: "#ffc107".to_string(),
+        },
+        security_notice: None,
+        acknowledgment_required: false,
+        acknowledgment_timeout_secs: None,
+        auto_hide_seconds: Some(DROP_NOTICE_SECONDS),
+    })
+}
+
+/// Wrap a monitor protocol message for the relay
+fn monitor_control_message(session_id: &str, message: MonitorControlMessage) -> RelayMessage {
+    RelayMessage::MonitorControl {
+        session_id: session_id.to_string(),
+        // Plain data enum; serialization cannot fail
+        data: serde_json::to_value(message).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    /// Sessions without capture or input, for machines with no display
+    struct DetachedSessions;
+
+    #[async_trait::async_trait]
+    impl SessionOpener for DetachedSessions {
+        async fn open(
+            &self,
+            id: String,
+            session_type: SessionType,
+            config: &ClientConfig,
+            _relay: &RwLock<Option<RelayConnection>>,
+        ) -> std::result::Result<(Session, SessionReadiness), SessionReadiness> {
+            Ok((Session::detached(id, session_type, config), SessionReadiness::default()))
+        }
+    }
+
+    #[test]
+    fn test_session_type_parsing() {
+        assert_eq!("backstage".parse::<SessionType>().unwrap(), SessionType::Backstage);
+        assert_eq!("Console".parse::<SessionType>().unwrap(), SessionType::Console);
+        assert_eq!("adhoc".parse::<SessionType>().unwrap(), SessionType::AdHoc);
+        assert!("bogus".parse::<SessionType>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_session_type_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Mock relay server: send a session request and wait for the response
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            let request = RelayMessage::SessionRequest {
+                session_id: "test-session".to_string(),
+                session_type: "bogus".to_string(),
+                requester: "technician".to_string(),
+                record: None,
+                capabilities: None,
+                banner: None,
+                requires_consent: false,
+                bandwidth_kbps: None,
+                compress_frames: false,
+            };
+            ws.send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Text(text) = msg {
+                    if let Ok(response @ RelayMessage::SessionResponse { .. }) = serde_json::from_str(&text) {
+                        return response;
+                    }
+                }
+            }
+            panic!("connection closed before session response");
+        });
+
+        let config = ClientConfig::new(format!("ws://{}", addr), Some("Test Device".to_string())).unwrap();
+        let mut agent = Agent::new(config).unwrap();
+        let agent_task = tokio::spawn(async move { agent.start().await });
+
+        let response = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("timed out waiting for session response")
+            .unwrap();
+        agent_task.abort();
+
+        match response {
+            RelayMessage::SessionResponse { session_id, accepted, reason, .. } => {
+                assert_eq!(session_id, "test-session");
+                assert!(!accepted);
+                let unsupported: GhostLinkError = SessionError::UnsupportedType { session_type: "bogus".to_string() }.into();
+                assert_eq!(reason, Some(unsupported.to_string()));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    #[tokio::test]
+    async fn test_requested_session_is_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Mock relay server: send a session request and wait for the response
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            let request = RelayMessage::SessionRequest {
+                session_id: "test-session".to_string(),
+                session_type: "console".to_string(),
+                requester: "technician".to_string(),
+                record: None,
+                capabilities: None,
+                banner: None,
+                requires_consent: false,
+                bandwidth_kbps: None,
+                compress_frames: false,
+            };
+            ws.send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Text(text) = msg {
+                    if let Ok(response @ RelayMessage::SessionResponse { .. }) = serde_json::from_str(&text) {
+                        return response;
+                    }
+                }
+            }
+            panic!("connection closed before session response");
+        });
+
+        let config = ClientConfig::new(format!("ws://{}", addr), Some("Test Device".to_string())).unwrap();
+        let mut agent = Agent::new(config).unwrap();
+        agent.sessions = Arc::new(DetachedSessions);
+        let sessions = Arc::clone(&agent.session_manager);
+        let agent_task = tokio::spawn(async move { agent.start().await });
+
+        let response = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("timed out waiting for session response")
+            .unwrap();
+        agent_task.abort();
+
+        match response {
+            RelayMessage::SessionResponse { session_id, accepted, reason, readiness, .. } => {
+                assert_eq!(session_id, "test-session");
+                assert!(accepted, "{:?}", reason);
+                assert!(readiness.is_some_and(|readiness| readiness.ready()));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        let session = sessions.get_session("test-session").await.expect("session was not created");
+        assert_eq!(session.session_type(), SessionType::Console);
+    }
+
+    #[tokio::test]
+    async fn test_elevation_for_unknown_session_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Mock relay server: relay a PAM approval and wait for the agent's answer
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            let request = serde_json::json!({
+                "type": "ElevationRequest",
+                "session_id": "missing-session",
+                "level": "RunAsAdmin",
+            });
+            ws.send(Message::Text(request.to_string())).await.unwrap();
+
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Text(text) = msg {
+                    if let Ok(result @ RelayMessage::ElevationResult { .. }) = serde_json::from_str(&text) {
+                        return result;
+                    }
+                }
+            }
+            panic!("connection closed before elevation result");
+        });
+
+        let config = ClientConfig::new(format!("ws://{}", addr), Some("Test Device".to_string())).unwrap();
+        let mut agent = Agent::new(config).unwrap();
+        let agent_task = tokio::spawn(async move { agent.start().await });
+
+        let result = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("timed out waiting for elevation result")
+            .unwrap();
+        agent_task.abort();
+
+        match result {
+            RelayMessage::ElevationResult { session_id, success, denied, method, error, .. } => {
+                assert_eq!(session_id, "missing-session");
+                assert!(!success);
+                assert!(!denied);
+                assert!(method.is_none());
+                assert!(error.unwrap().contains("not found"));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    /// Shut down an agent with two recorded sessions against a mock relay
+    /// that acknowledges ended sessions if `ack`, returning what the relay
+    /// saw and how long shutdown took
+    async fn shutdown_with_sessions(ack: bool) -> (Vec<String>, std::time::Duration) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let recordings = dir.path().to_path_buf();
+        let finalized = move || {
+            crate::recording::list_recordings(&recordings)
+                .map(|list| list.iter().filter(|metadata| metadata.end_time.is_some()).count())
+                .unwrap_or(0)
+        };
+
+        // Mock relay server: log the shutdown sequence and the recordings
+        // finalized at each step
+        let (registered_tx, registered_rx) = tokio::sync::oneshot::channel();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut registered_tx = Some(registered_tx);
+            let mut events = Vec::new();
+
+            while let Some(Ok(msg)) = ws.next().await {
+                match msg {
+                    Message::Text(text) => match serde_json::from_str(&text) {
+                        Ok(RelayMessage::AgentRegister { .. }) => {
+                            if let Some(tx) = registered_tx.take() {
+                                let _ = tx.send(());
+                            }
+                        }
+                        Ok(RelayMessage::SessionEnd { session_id, .. }) => {
+                            events.push(format!("SessionEnd {} ({} finalized)", session_id, finalized()));
+                            if ack {
+                                let echo = RelayMessage::SessionEnd { session_id, reason: None, error_code: None };
+                                ws.send(Message::Text(serde_json::to_string(&echo).unwrap())).await.unwrap();
+                            }
+                        }
+                        Ok(RelayMessage::AgentDeregister { reason, .. }) => {
+                            events.push(format!("AgentDeregister {} ({} finalized)", reason, finalized()));
+                        }
+                        _ => {}
+                    },
+                    Message::Close(frame) => {
+                        events.push(format!("Close {:?}", frame.map(|frame| u16::from(frame.code))));
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            events
+        });
+
+        let mut config = ClientConfig::new(format!("ws://{}", addr), Some("Test Device".to_string())).unwrap();
+        config.recording.recording_directory = dir.path().to_path_buf();
+        config.recording.min_free_space_bytes = 0;
+        let mut agent = Agent::new(config.clone()).unwrap();
+        for session_id in ["session-a", "session-b"] {
+            let session = Session::detached(session_id.to_string(), SessionType::Console, &config);
+            session.start_recording(OperatorInfo::from_requester("technician")).await.unwrap();
+            agent.session_manager.add_session(session_id.to_string(), session).await.unwrap();
+        }
+        let shutdown = agent.shutdown_handle();
+        let agent_task = tokio::spawn(async move { agent.start().await });
+
+        tokio::time::timeout(Duration::from_secs(5), registered_rx)
+            .await
+            .expect("timed out waiting for registration")
+            .unwrap();
+        let started = Instant::now();
+        shutdown.send(()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), agent_task)
+            .await
+            .expect("shutdown did not finish")
+            .unwrap()
+            .unwrap();
+        let elapsed = started.elapsed();
+
+        let mut events = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        // Sessions end in map order
+        events[..2].sort();
+        (events, elapsed)
+    }
+
+    async fn ask(control: &mpsc::Sender<ControlCommand>, request: ControlRequest) -> ControlResponse {
+        let (reply, answer) = oneshot::channel();
+        control.send(ControlCommand { request, reply }).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), answer).await.unwrap().unwrap()
+    }
+
+    /// The next message the agent sent the mock relay that `wanted` picks
+    async fn next_seen(seen: &mut mpsc::UnboundedReceiver<RelayMessage>, wanted: fn(&RelayMessage) -> bool) -> RelayMessage {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let message = seen.recv().await.expect("relay closed");
+                if wanted(&message) {
+                    return message;
+                }
+            }
+        }).await.expect("timed out waiting for the agent")
+    }
+
+    #[tokio::test]
+    async fn test_user_disconnect_and_pause() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Mock relay server: hand out a policy that keeps backstage sessions
+        // from the end user, try a session once access is paused, and pass
+        // on everything the agent sends
+        let (seen_tx, mut seen) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                let Message::Text(text) = msg else { continue };
+                let Ok(message) = serde_json::from_str::<RelayMessage>(&text) else { continue };
+                let reply = match &message {
+                    RelayMessage::AgentRegister { .. } => Some(RelayMessage::PolicyUpdate {
+                        policy: ServerPolicy {
+                            user_disconnect: Some(crate::policy::UserDisconnect::Attended),
+                            max_user_pause_hours: Some(8),
+                            ..Default::default()
+                        },
+                    }),
+                    RelayMessage::AccessPaused { until: Some(_) } => Some(RelayMessage::SessionRequest {
+                        session_id: "late-session".to_string(),
+                        session_type: "console".to_string(),
+                        requester: "technician".to_string(),
+                        record: None,
+                        capabilities: None,
+                        banner: None,
+                        requires_consent: false,
+                        bandwidth_kbps: None,
+                        compress_frames: false,
+                    }),
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    ws.send(Message::Text(serde_json::to_string(&reply).unwrap())).await.unwrap();
+                }
+                let _ = seen_tx.send(message);
+            }
+        });
+        let config = ClientConfig::new(format!("ws://{}", addr), Some("Test Device".to_string())).unwrap();
+        let mut agent = Agent::new(config.clone()).unwrap();
+        for (session_id, session_type) in [("attended", SessionType::Console), ("backstage", SessionType::Backstage)] {
+            let session = Session::detached(session_id.to_string(), session_type, &config);
+            session.set_technician("tech@example.com");
+            agent.session_manager.add_session(session_id.to_string(), session).await.unwrap();
+        }
+        let control = agent.control_handle();
+        let agent_task = tokio::spawn(async move { agent.start().await });
+
+        // The status follows the policy once it arrives
+        let status = loop {
+            let ControlResponse::Status { status } = ask(&control, ControlRequest::Status).await else {
+                panic!("expected a status");
+            };
+            if status.max_pause_hours == Some(8) {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(status.sessions.len(), 2);
+        assert!(status.sessions.iter().all(|session| session.technician.as_deref() == Some("tech@example.com")));
+        let may_disconnect = |id: &str| status.sessions.iter().find(|session| session.session_id == id).unwrap().may_disconnect;
+        assert!(may_disconnect("attended"));
+        assert!(!may_disconnect("backstage"));
+
+        // Policy keeps the end user from ending the backstage session
+        let response = ask(&control, ControlRequest::Disconnect { session_id: "backstage".to_string() }).await;
+        assert!(matches!(response, ControlResponse::Error { .. }), "{:?}", response);
+        assert!(matches!(ask(&control, ControlRequest::Pause { hours: 9 }).await, ControlResponse::Error { .. }));
+
+        // Pausing ends both, and the relay hears about the pause
+        assert_eq!(ask(&control, ControlRequest::Pause { hours: 2 }).await, ControlResponse::Done);
+        let mut ended = Vec::new();
+        for _ in 0..2 {
+            let RelayMessage::SessionEnd { session_id, reason, .. } = next_seen(&mut seen, |m| matches!(m, RelayMessage::SessionEnd { .. })).await else { unreachable!() };
+            assert_eq!(reason.as_deref(), Some("Remote access paused by the user"));
+            ended.push(session_id);
+        }
+        ended.sort();
+        assert_eq!(ended, ["attended", "backstage"]);
+        let RelayMessage::AccessPaused { until } = next_seen(&mut seen, |m| matches!(m, RelayMessage::AccessPaused { .. })).await else { unreachable!() };
+        let until = until.unwrap();
+        assert!(until > chrono::Utc::now() + chrono::Duration::minutes(119));
+
+        // ...and no session starts while paused
+        let RelayMessage::SessionResponse { session_id, accepted, reason, .. } = next_seen(&mut seen, |m| matches!(m, RelayMessage::SessionResponse { .. })).await else { unreachable!() };
+        assert_eq!(session_id, "late-session");
+        assert!(!accepted);
+        assert!(reason.unwrap().contains("paused"));
+        let ControlResponse::Status { status } = ask(&control, ControlRequest::Status).await else { panic!() };
+        assert_eq!(status.paused_until, Some(until));
+        assert!(status.sessions.is_empty());
+
+        assert_eq!(ask(&control, ControlRequest::Resume).await, ControlResponse::Done);
+        let message = next_seen(&mut seen, |m| matches!(m, RelayMessage::AccessPaused { .. })).await;
+        assert!(matches!(message, RelayMessage::AccessPaused { until: None }));
+
+        agent_task.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sequence() {
+        let (events, elapsed) = shutdown_with_sessions(true).await;
+        assert_eq!(events, vec![
+            "SessionEnd session-a (0 finalized)",
+            "SessionEnd session-b (0 finalized)",
+            "AgentDeregister shutdown (2 finalized)",
+            "Close Some(1000)",
+        ]);
+        assert!(elapsed < SESSION_END_TIMEOUT, "acknowledged sessions shouldn't wait out the timeout");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_without_acks_is_bounded() {
+        let (events, elapsed) = shutdown_with_sessions(false).await;
+        assert_eq!(events[2..], [
+            "AgentDeregister shutdown (2 finalized)",
+            "Close Some(1000)",
+        ]);
+        assert!(elapsed >= SESSION_END_TIMEOUT);
+        assert!(elapsed < SESSION_END_TIMEOUT + Duration::from_secs(2), "{:?}", elapsed);
+    }
 }
@@ -1,20 +1,57 @@
-use anyhow::Result;
+use crate::error::{GhostLinkError, Result, SessionError};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use tracing::{error, info, warn};
 
+use crate::logging::crash;
 use crate::session::{Session, SessionType};
 
+/// Maximum number of input events queued per session before new ones are dropped
+const INPUT_QUEUE_SIZE: usize = 256;
+
+/// Input event queued for a session: relay event type and JSON payload
+type InputEnvelope = (String, serde_json::Value);
+
+/// Which session may inject input, as last announced by the relay
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum ControlToken {
+    /// The relay never announced a holder; every session may send input
+    #[default]
+    Unmanaged,
+    /// Only this session may send input, or none while unset
+    Held(Option<String>),
+}
+
 /// Manages multiple concurrent remote sessions
 pub struct SessionManager {
     sessions: Arc<RwLock<HashMap<String, Session>>>,
+    /// Per-session input queues, kept apart from `sessions` so input routing
+    /// never contends with session lifecycle changes
+    input_routes: Arc<RwLock<HashMap<String, mpsc::Sender<InputEnvelope>>>>,
+    control: Arc<RwLock<ControlToken>>,
 }
 
 impl SessionManager {
     pub fn new() -> Self {
         Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            input_routes: Arc::new(RwLock::new(HashMap::new())),
+            control: Arc::new(RwLock::new(ControlToken::default())),
+        }
+    }
+
+    /// Record the session the relay handed input control to
+    pub async fn set_control_holder(&self, holder: Option<String>) {
+        info!("Input control now held by {}", holder.as_deref().unwrap_or("no session"));
+        *self.control.write().await = ControlToken::Held(holder);
+    }
+
+    /// Whether the relay lets `session_id` send input now
+    pub async fn may_send_input(&self, session_id: &str) -> bool {
+        match &*self.control.read().await {
+            ControlToken::Unmanaged => true,
+            ControlToken::Held(holder) => holder.as_deref() == Some(session_id),
         }
     }
 
@@ -26,14 +63,71 @@ impl SessionManager {
             warn!("Session {} already exists, replacing", session_id);
         }
         
+        let input_tx = Self::spawn_input_worker(session.clone());
         sessions.insert(session_id.clone(), session);
+        self.input_routes.write().await.insert(session_id.clone(), input_tx);
+        crash::set_active_sessions(sessions.keys().cloned().collect());
         info!("Added session: {}", session_id);
         
         Ok(())
     }
 
+    /// Spawn the task that feeds queued input events into a session's input controller
+    fn spawn_input_worker(session: Session) -> mpsc::Sender<InputEnvelope> {
+        let (input_tx, mut input_rx) = mpsc::channel::<InputEnvelope>(INPUT_QUEUE_SIZE);
+        
+        tokio::spawn(async move {
+            while let Some((event_type, data)) = input_rx.recv().await {
+                session.record_input(&event_type, &data).await;
+                let result = if event_type == "viewer_resolution" {
+                    session.set_viewer_resolution(&data).await
+                } else {
+                    session.handle_input_event(&data).await
+                };
+                
+                if let Err(e) = result {
+                    warn!("Input event failed for session {}: {}", session.id, e);
+                }
+            }
+        });
+        
+        input_tx
+    }
+
+    /// Queue an input event for a session without blocking on the session map
+    pub async fn route_input_event(
+        &self,
+        session_id: &str,
+        event_type: String,
+        data: serde_json::Value,
+    ) -> Result<()> {
+        // The viewer's resolution is not input and may come from any session
+        if event_type != "viewer_resolution" {
+            if let ControlToken::Held(holder) = &*self.control.read().await {
+                if holder.as_deref() != Some(session_id) {
+                    return Err(GhostLinkError::Other(format!("Session {} does not hold input control", session_id)));
+                }
+            }
+        }
+
+        let routes = self.input_routes.read().await;
+        let input_tx = routes.get(session_id).ok_or_else(|| SessionError::NotFound {
+            session_id: session_id.to_string(),
+        })?;
+        
+        input_tx.try_send((event_type, data)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                GhostLinkError::Other(format!("Input queue full for session {}", session_id))
+            }
+            mpsc::error::TrySendError::Closed(_) => SessionError::NotFound {
+                session_id: session_id.to_string(),
+            }.into(),
+        })
+    }
+
     /// Remove and stop a session
     pub async fn remove_session(&self, session_id: &str) -> Result<()> {
+        self.input_routes.write().await.remove(session_id);
         let mut sessions = self.sessions.write().await;
         
         if let Some(session) = sessions.remove(session_id) {
@@ -41,6 +135,7 @@ impl SessionManager {
             if let Err(e) = session.stop().await {
                 error!("Error stopping session {}: {}", session_id, e);
             }
+            crash::set_active_sessions(sessions.keys().cloned().collect());
             info!("Removed session: {}", session_id);
         } else {
             warn!("Attempted to remove non-existent session: {}", session_id);
@@ -93,6 +188,7 @@ impl SessionManager {
     pub async fn shutdown_all(&self) -> Result<()> {
         info!("Shutting down all sessions");
         
+        self.input_routes.write().await.clear();
         let mut sessions = self.sessions.write().await;
         let session_ids: Vec<String> = sessions.keys().cloned().collect();
         
@@ -103,6 +199,7 @@ impl SessionManager {
                 }
             }
         }
+        crash::set_active_sessions(Vec::new());
         
         info!("All sessions shut down");
         Ok(())
@@ -0,0 +1,578 @@
+//! Adaptive frame rate and bitrate for the capture loop
+//!
+//! The controller is fed the time each frame took to capture and encode and
+//! the number of encoded frames still waiting to be sent. When the pipeline
+//! falls behind it cuts the frame rate multiplicatively, and the bitrate too
+//! if the relay is the bottleneck; once the pipeline has kept up for a while
+//! it raises both again in small steps, up to the ceiling set by the
+//! technician's quality setting. Link measurements from the quality monitor
+//! count too: loss, or round trips well above the best seen, cut the bitrate
+//! and hold off the ramp-up.
+//!
+//! A session's bandwidth cap lowers both ceilings: the bitrate to a share of
+//! the cap, the frame rate to what the cap can carry. Under a few hundred
+//! kbit/s frames lose their chroma too. The encoder profile may hold the
+//! frame rate and color depth lower still.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::bandwidth::ColorDepth;
+use super::encoder_factory::SkippedEncoder;
+use super::encoder_profile::EncoderSettings;
+use super::BufferPath;
+
+/// Queued frames at which the relay counts as congested
+const CONGESTED_QUEUE_DEPTH: usize = 2;
+
+/// Share of the frame interval capture and encode may use before the
+/// pipeline counts as behind
+const BUSY_RATIO: f64 = 0.9;
+
+/// Share of the frame interval below which there is room to speed up
+const IDLE_RATIO: f64 = 0.6;
+
+/// Consecutive frames with headroom before stepping up
+const RAMP_UP_FRAMES: u32 = 30;
+
+/// Frames to wait after a cut before cutting again, so its effect shows
+const COOLDOWN_FRAMES: u32 = 5;
+
+const DECREASE_FACTOR: f64 = 0.75;
+const FPS_STEP: f64 = 2.0;
+const BITRATE_STEP: f64 = 0.1;
+
+/// Weight of the newest sample in the average frame time
+const AVERAGE_WEIGHT: f64 = 0.2;
+
+/// Relative bitrate change worth passing on to the encoder; hardware
+/// encoders restart their stream to apply a new rate
+const BITRATE_HINT_THRESHOLD: f64 = 0.2;
+
+const INITIAL_BITRATE_KBPS: u32 = 2000;
+
+/// Probe loss at which the link counts as congested
+const LOSSY_PERCENT: f64 = 5.0;
+
+/// Round trips above this multiple of the best seen, plus the slack, mean
+/// packets are queueing somewhere on the path
+const RTT_INFLATION: f64 = 2.0;
+const RTT_SLACK_MS: f64 = 50.0;
+
+/// Share of a bandwidth cap the encoder may use; the rest carries frame
+/// headers, cursor updates and control messages
+const ENCODER_SHARE: f64 = 0.85;
+
+/// Bandwidth a frame per second needs at the lowest useful quality
+const KBPS_PER_FPS: f64 = 50.0;
+
+/// Caps below which frames are sent in grayscale
+const GRAYSCALE_BELOW_KBPS: u32 = 256;
+
+/// Bounds the controller stays within
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveConfig {
+    #[serde(deserialize_with = "crate::config::validate::fps")]
+    pub min_fps: u32,
+    /// Frame rate cap; a config reload changes it for running sessions
+    #[serde(deserialize_with = "crate::config::validate::fps")]
+    pub max_fps: u32,
+    pub min_bitrate_kbps: u32,
+    pub max_bitrate_kbps: u32,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self {
+            min_fps: 5,
+            max_fps: 60,
+            min_bitrate_kbps: 300,
+            max_bitrate_kbps: 8000,
+        }
+    }
+}
+
+/// Timing of one pass through the capture loop
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSample {
+    /// Time spent capturing and encoding the frame
+    pub busy: Duration,
+    /// Encoded frames waiting to be sent
+    pub queue_depth: usize,
+}
+
+/// Current state of the capture pipeline
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CaptureStats {
+    /// Target frame rate
+    pub fps: u32,
+    /// Target bitrate handed to the encoder
+    pub bitrate_kbps: u32,
+    /// Encoded frames waiting to be sent
+    pub queue_depth: usize,
+    /// Average capture and encode time per frame
+    pub frame_time_ms: f64,
+    /// Time the last screen grab took, before diffing and encoding
+    pub grab_time_ms: f64,
+    /// How the capturer hands frames over, e.g. shared memory or DMA-BUF
+    pub buffer_path: BufferPath,
+    /// Quality setting from the technician, 0-100
+    pub quality: u8,
+    pub frames_sent: u64,
+    /// Frames dropped because the outbound queue was full
+    pub frames_dropped: u64,
+    /// Frames skipped because nothing on screen changed
+    pub frames_unchanged: u64,
+    /// Frames sent in full after a resize, refresh interval or viewer join
+    pub full_refreshes: u64,
+    /// Name of the encoder in use
+    pub encoder: String,
+    /// Better encoders that failed their probe on this machine
+    pub skipped_encoders: Vec<SkippedEncoder>,
+    /// Bandwidth cap in force, if any
+    pub bandwidth_cap_kbps: Option<u32>,
+    /// Rate frames actually went out at
+    pub throughput_kbps: u32,
+    /// Capture and encode time of all sessions, in percent of one core
+    pub cpu_percent: f64,
+    /// CPU budget in force, if any
+    pub cpu_budget_percent: Option<u32>,
+    /// Frames of all sessions delayed to stay within the CPU budget
+    pub frames_throttled: u64,
+    /// Bytes frame and recording buffers of all sessions hold
+    pub memory_used: u64,
+    /// Memory ceiling in force, if any
+    pub memory_limit: Option<u64>,
+    /// Frames dropped to stay under the memory ceiling
+    pub frames_shed: u64,
+}
+
+/// Steers frame rate and bitrate from capture loop feedback
+#[derive(Debug)]
+pub struct AdaptiveController {
+    config: AdaptiveConfig,
+    fps: f64,
+    bitrate_kbps: f64,
+    quality: u8,
+    average_busy: Option<f64>,
+    queue_depth: usize,
+    headroom_frames: u32,
+    cooldown: u32,
+    applied_bitrate_kbps: Option<u32>,
+    /// Lowest round trip reported by the quality monitor
+    base_rtt_ms: Option<f64>,
+    /// Whether the last link measurement showed congestion
+    link_congested: bool,
+    bandwidth_cap_kbps: Option<u32>,
+    /// Frame rate the encoder profile holds the stream to
+    profile_max_fps: Option<u32>,
+    /// Color depth the encoder profile asks for
+    profile_color: ColorDepth,
+}
+
+impl AdaptiveController {
+    pub fn new(config: AdaptiveConfig, initial_fps: u32) -> Self {
+        let min_fps = config.min_fps.max(1);
+        let min_bitrate = config.min_bitrate_kbps.max(1);
+        let config = AdaptiveConfig {
+            min_fps,
+            max_fps: config.max_fps.max(min_fps),
+            min_bitrate_kbps: min_bitrate,
+            max_bitrate_kbps: config.max_bitrate_kbps.max(min_bitrate),
+        };
+
+        Self {
+            fps: initial_fps.clamp(config.min_fps, config.max_fps) as f64,
+            bitrate_kbps: INITIAL_BITRATE_KBPS.clamp(config.min_bitrate_kbps, config.max_bitrate_kbps) as f64,
+            config,
+            quality: 100,
+            average_busy: None,
+            queue_depth: 0,
+            headroom_frames: 0,
+            cooldown: 0,
+            applied_bitrate_kbps: None,
+            base_rtt_ms: None,
+            link_congested: false,
+            bandwidth_cap_kbps: None,
+            profile_max_fps: None,
+            profile_color: ColorDepth::Full,
+        }
+    }
+
+    /// Time until the next frame should be captured
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.fps)
+    }
+
+    pub fn fps(&self) -> u32 {
+        self.fps.round() as u32
+    }
+
+    pub fn bitrate_kbps(&self) -> u32 {
+        self.bitrate_kbps.round() as u32
+    }
+
+    fn fps_ceiling(&self) -> f64 {
+        let range = (self.config.max_fps - self.config.min_fps) as f64;
+        let mut ceiling = self.config.min_fps as f64 + range * self.quality as f64 / 100.0;
+        if let Some(max_fps) = self.profile_max_fps {
+            ceiling = ceiling.min(max_fps as f64);
+        }
+        if let Some(kbps) = self.bandwidth_cap_kbps {
+            ceiling = ceiling.min(kbps as f64 / KBPS_PER_FPS);
+        }
+        ceiling.max(1.0)
+    }
+
+    fn bitrate_ceiling(&self) -> f64 {
+        let range = (self.config.max_bitrate_kbps - self.config.min_bitrate_kbps) as f64;
+        let ceiling = self.config.min_bitrate_kbps as f64 + range * self.quality as f64 / 100.0;
+        match self.bandwidth_cap_kbps {
+            Some(kbps) => ceiling.min(kbps as f64 * ENCODER_SHARE),
+            None => ceiling,
+        }
+    }
+
+    /// The configured floors give way to a cap or profile below them
+    fn fps_floor(&self) -> f64 {
+        (self.config.min_fps as f64).min(self.fps_ceiling())
+    }
+
+    fn bitrate_floor(&self) -> f64 {
+        (self.config.min_bitrate_kbps as f64).min(self.bitrate_ceiling())
+    }
+
+    fn clamp_to_ceilings(&mut self) {
+        self.fps = self.fps.min(self.fps_ceiling());
+        self.bitrate_kbps = self.bitrate_kbps.min(self.bitrate_ceiling());
+        self.headroom_frames = 0;
+    }
+
+    /// Map the technician's quality setting (0-100) onto the ceilings the
+    /// controller may ramp up to
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality.min(100);
+        self.clamp_to_ceilings();
+    }
+
+    /// Change the highest frame rate the controller may ramp up to, e.g.
+    /// when a new policy arrives mid-session. A floor above the new cap
+    /// comes down with it.
+    pub fn set_max_fps(&mut self, max_fps: u32) {
+        self.config.max_fps = max_fps.max(1);
+        self.config.min_fps = self.config.min_fps.min(self.config.max_fps);
+        self.fps = self.fps.min(self.fps_ceiling());
+        self.headroom_frames = 0;
+    }
+
+    /// Keep frame rate and bitrate within `kbps`, or lift the cap with `None`
+    pub fn set_bandwidth_cap(&mut self, kbps: Option<u32>) {
+        self.bandwidth_cap_kbps = kbps;
+        self.clamp_to_ceilings();
+    }
+
+    /// Take on the frame rate cap and color depth of an encoder profile
+    pub fn apply_profile(&mut self, settings: &EncoderSettings) {
+        self.profile_max_fps = settings.max_fps;
+        self.profile_color = settings.color_depth;
+        self.clamp_to_ceilings();
+    }
+
+    /// Color depth frames should be reduced to before encoding
+    pub fn color_depth(&self) -> ColorDepth {
+        let capped = match self.bandwidth_cap_kbps {
+            Some(kbps) if kbps < GRAYSCALE_BELOW_KBPS => ColorDepth::Grayscale,
+            _ => ColorDepth::Full,
+        };
+        self.profile_color.max(capped)
+    }
+
+    /// Feed the timing of the frame just captured
+    pub fn record(&mut self, sample: FrameSample) {
+        let busy = sample.busy.as_secs_f64();
+        let average = match self.average_busy {
+            Some(average) => average + AVERAGE_WEIGHT * (busy - average),
+            None => busy,
+        };
+        self.average_busy = Some(average);
+        self.queue_depth = sample.queue_depth;
+        self.cooldown = self.cooldown.saturating_sub(1);
+
+        let budget = 1.0 / self.fps;
+        let congested = sample.queue_depth >= CONGESTED_QUEUE_DEPTH;
+        if congested || average > budget * BUSY_RATIO {
+            self.headroom_frames = 0;
+            if self.cooldown == 0 {
+                self.fps = (self.fps * DECREASE_FACTOR).max(self.fps_floor());
+                // A slow encoder is not helped by a lower bitrate, a slow network is
+                if congested {
+                    self.bitrate_kbps = (self.bitrate_kbps * DECREASE_FACTOR).max(self.bitrate_floor());
+                }
+                self.cooldown = COOLDOWN_FRAMES;
+            }
+            return;
+        }
+
+        if sample.queue_depth == 0 && average < budget * IDLE_RATIO && !self.link_congested {
+            self.headroom_frames += 1;
+            if self.headroom_frames >= RAMP_UP_FRAMES {
+                self.headroom_frames = 0;
+                self.fps = (self.fps + FPS_STEP).min(self.fps_ceiling().max(self.fps));
+                self.bitrate_kbps = (self.bitrate_kbps * (1.0 + BITRATE_STEP))
+                    .min(self.bitrate_ceiling().max(self.bitrate_kbps));
+            }
+        } else {
+            self.headroom_frames = 0;
+        }
+    }
+
+    /// Feed the latest round-trip time and loss measured on the link
+    pub fn record_link(&mut self, rtt_ms: f64, loss_percent: f64) {
+        if rtt_ms > 0.0 {
+            self.base_rtt_ms = Some(self.base_rtt_ms.map_or(rtt_ms, |base| base.min(rtt_ms)));
+        }
+        let base = self.base_rtt_ms.unwrap_or(rtt_ms);
+        self.link_congested = loss_percent >= LOSSY_PERCENT || rtt_ms > base * RTT_INFLATION + RTT_SLACK_MS;
+
+        if self.link_congested {
+            self.headroom_frames = 0;
+            if self.cooldown == 0 {
+                self.bitrate_kbps = (self.bitrate_kbps * DECREASE_FACTOR).max(self.bitrate_floor());
+                self.cooldown = COOLDOWN_FRAMES;
+            }
+        }
+    }
+
+    /// New bitrate for the encoder, if it moved far enough from the last one
+    pub fn take_bitrate_hint(&mut self) -> Option<u32> {
+        let current = self.bitrate_kbps();
+        let changed = match self.applied_bitrate_kbps {
+            Some(applied) => (current as f64 - applied as f64).abs() / applied as f64 >= BITRATE_HINT_THRESHOLD,
+            None => true,
+        };
+
+        if changed {
+            self.applied_bitrate_kbps = Some(current);
+            Some(current)
+        } else {
+            None
+        }
+    }
+
+    /// Copy the controller's view into `stats`
+    pub fn fill_stats(&self, stats: &mut CaptureStats) {
+        stats.fps = self.fps();
+        stats.bitrate_kbps = self.applied_bitrate_kbps.unwrap_or_else(|| self.bitrate_kbps());
+        stats.queue_depth = self.queue_depth;
+        stats.frame_time_ms = self.average_busy.unwrap_or(0.0) * 1000.0;
+        stats.quality = self.quality;
+        stats.bandwidth_cap_kbps = self.bandwidth_cap_kbps;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(busy_ms: u64, queue_depth: usize) -> FrameSample {
+        FrameSample {
+            busy: Duration::from_millis(busy_ms),
+            queue_depth,
+        }
+    }
+
+    fn controller() -> AdaptiveController {
+        AdaptiveController::new(AdaptiveConfig::default(), 30)
+    }
+
+    #[test]
+    fn test_slow_encode_lowers_fps_only() {
+        let mut controller = controller();
+        let bitrate = controller.bitrate_kbps();
+
+        // 50ms per frame cannot sustain 30fps
+        for _ in 0..100 {
+            controller.record(sample(50, 0));
+        }
+
+        // Settles where 50ms fits the frame interval, without overshooting to the floor
+        assert!(controller.fps() < 18, "{}", controller.fps());
+        assert!(controller.fps() > 5, "{}", controller.fps());
+        assert_eq!(controller.bitrate_kbps(), bitrate);
+    }
+
+    #[test]
+    fn test_congestion_ramps_down_then_recovers() {
+        let mut controller = controller();
+        assert_eq!(controller.take_bitrate_hint(), Some(2000));
+
+        for _ in 0..200 {
+            controller.record(sample(2, 4));
+        }
+        assert_eq!(controller.fps(), 5);
+        assert_eq!(controller.bitrate_kbps(), 300);
+        assert_eq!(controller.take_bitrate_hint(), Some(300));
+        assert_eq!(controller.take_bitrate_hint(), None);
+
+        // Each step up needs a run of frames with headroom
+        for _ in 0..RAMP_UP_FRAMES - 1 {
+            controller.record(sample(2, 0));
+        }
+        assert_eq!(controller.fps(), 5);
+        controller.record(sample(2, 0));
+        assert_eq!(controller.fps(), 7);
+
+        // A busy frame restarts the run
+        for _ in 0..RAMP_UP_FRAMES - 1 {
+            controller.record(sample(2, 0));
+        }
+        controller.record(sample(2, 1));
+        controller.record(sample(2, 0));
+        assert_eq!(controller.fps(), 7);
+
+        for _ in 0..RAMP_UP_FRAMES * 40 {
+            controller.record(sample(2, 0));
+        }
+        assert_eq!(controller.fps(), 60);
+        assert_eq!(controller.bitrate_kbps(), 8000);
+    }
+
+    #[test]
+    fn test_quality_caps_ramp_up() {
+        let mut controller = controller();
+        controller.set_quality(0);
+        assert_eq!(controller.fps(), 5);
+        assert_eq!(controller.bitrate_kbps(), 300);
+
+        controller.set_quality(50);
+        for _ in 0..RAMP_UP_FRAMES * 40 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), 33);
+        assert_eq!(controller.bitrate_kbps(), 4150);
+        assert_eq!(controller.interval(), Duration::from_secs_f64(1.0 / 32.5));
+
+        let mut stats = CaptureStats::default();
+        controller.fill_stats(&mut stats);
+        assert_eq!(stats.quality, 50);
+        assert_eq!(stats.queue_depth, 0);
+    }
+
+    #[test]
+    fn test_max_fps_changes_at_runtime() {
+        let mut controller = controller();
+        for _ in 0..RAMP_UP_FRAMES * 20 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), 60);
+
+        controller.set_max_fps(15);
+        assert_eq!(controller.fps(), 15);
+        for _ in 0..RAMP_UP_FRAMES * 5 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), 15);
+
+        // A cap below the floor takes the floor down too
+        controller.set_max_fps(2);
+        assert_eq!(controller.fps(), 2);
+        for _ in 0..200 {
+            controller.record(sample(2, 4));
+        }
+        assert_eq!(controller.fps(), 2);
+
+        // Lifting the cap lets the controller ramp back up
+        controller.set_max_fps(30);
+        for _ in 0..RAMP_UP_FRAMES * 20 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), 30);
+    }
+
+    #[test]
+    fn test_link_quality_lowers_bitrate_and_holds_ramp_up() {
+        let mut controller = controller();
+        let fps = controller.fps();
+
+        controller.record_link(40.0, 0.0);
+        assert_eq!(controller.bitrate_kbps(), 2000, "a healthy link changes nothing");
+
+        // Loss cuts the bitrate but leaves the frame rate to the capture loop
+        controller.record_link(40.0, 20.0);
+        assert_eq!(controller.bitrate_kbps(), 1500);
+        assert_eq!(controller.fps(), fps);
+
+        // Frames with headroom don't ramp up while the link is congested
+        for _ in 0..RAMP_UP_FRAMES * 2 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), fps);
+
+        // Round trips far above the best seen count as congestion too
+        controller.record_link(200.0, 0.0);
+        assert_eq!(controller.bitrate_kbps(), 1125);
+
+        controller.record_link(45.0, 0.0);
+        for _ in 0..RAMP_UP_FRAMES {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), fps + 2);
+    }
+
+    #[test]
+    fn test_bandwidth_cap_lowers_ceilings() {
+        let mut controller = controller();
+        controller.set_bandwidth_cap(Some(500));
+        // 85% of the cap for the encoder, 10fps at 50 kbit/s each
+        assert_eq!(controller.bitrate_kbps(), 425);
+        assert_eq!(controller.fps(), 10);
+        assert_eq!(controller.color_depth(), ColorDepth::Full);
+        for _ in 0..RAMP_UP_FRAMES * 40 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), 10);
+        assert_eq!(controller.bitrate_kbps(), 425);
+
+        // A cap under the configured floors takes them down with it
+        controller.set_bandwidth_cap(Some(200));
+        assert_eq!(controller.color_depth(), ColorDepth::Grayscale);
+        for _ in 0..200 {
+            controller.record(sample(2, 4));
+        }
+        assert_eq!(controller.fps(), 4);
+        assert_eq!(controller.bitrate_kbps(), 170);
+        let mut stats = CaptureStats::default();
+        controller.fill_stats(&mut stats);
+        assert_eq!(stats.bandwidth_cap_kbps, Some(200));
+
+        controller.set_bandwidth_cap(None);
+        for _ in 0..RAMP_UP_FRAMES * 60 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), 60);
+        assert_eq!(controller.bitrate_kbps(), 8000);
+    }
+
+    #[test]
+    fn test_profile_holds_frame_rate_and_color() {
+        use crate::capture::encoder_profile::{CodecFamily, EncoderProfile};
+
+        let mut controller = controller();
+        controller.apply_profile(&EncoderProfile::LowBandwidth.settings(CodecFamily::Software));
+        assert_eq!(controller.fps(), 5);
+        assert_eq!(controller.color_depth(), ColorDepth::Palette256);
+        for _ in 0..RAMP_UP_FRAMES * 10 {
+            controller.record(sample(1, 0));
+        }
+        assert_eq!(controller.fps(), 5);
+
+        // A tight cap drops the palette for grayscale
+        controller.set_bandwidth_cap(Some(100));
+        assert_eq!(controller.color_depth(), ColorDepth::Grayscale);
+
+        controller.set_bandwidth_cap(None);
+        controller.apply_profile(&EncoderProfile::Balanced.settings(CodecFamily::Software));
+        assert_eq!(controller.color_depth(), ColorDepth::Full);
+    }
+}
@@ -0,0 +1,345 @@
+//! Per-session bandwidth caps
+//!
+//! A technician on a cellular hotspot can cap how much a session sends. The
+//! adaptive controller keeps the encoder's frame rate and bitrate under the
+//! cap, but encoders overshoot on scene changes and keyframes, so every
+//! encoded frame also passes a token bucket before it is sent. The bucket
+//! holds a quarter second's worth of bytes: a frame that would overdraw it
+//! waits until the cap has paid for it, which keeps the bytes sent in any
+//! window of `t` seconds under `burst + cap * t`. The wait backs up the
+//! outbound queue, which the controller reads as congestion.
+//!
+//! The server may impose an organization-wide ceiling; the effective cap is
+//! the lower of the two.
+//!
+//! Below a few hundred kbit/s the controller also drops the chroma, and the
+//! `low-bandwidth` profile reduces frames to 256 colors: either leaves the
+//! encoder far less detail to spend bits on, and far fewer tiles change
+//! between frames.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::{Frame, PixelFormat};
+
+/// Seconds of the cap the bucket may hold
+const BURST_SECONDS: f64 = 0.25;
+
+/// Window actual throughput is measured over
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(2);
+
+/// Slowest cap a session may run at
+pub const MIN_BANDWIDTH_KBPS: u32 = 64;
+
+/// How much color frames keep before they are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorDepth {
+    #[default]
+    Full,
+    /// 3 bits of red and green, 2 of blue
+    Palette256,
+    /// Luma only
+    Grayscale,
+}
+
+impl ColorDepth {
+    /// Reduce `frame` to this depth in place
+    pub fn apply(&self, frame: &mut Frame) {
+        match (self, frame.pixel_format) {
+            (ColorDepth::Full, _) => {}
+            (ColorDepth::Grayscale, PixelFormat::YUV420 | PixelFormat::NV12) => {
+                // Neutral chroma; the luma plane already is the gray image
+                let luma = frame.stride as usize * frame.height as usize;
+                if let Some(chroma) = frame.data.get_mut(luma..) {
+                    chroma.fill(128);
+                }
+            }
+            // Planar frames have no per-pixel color to quantize
+            (ColorDepth::Palette256, PixelFormat::YUV420 | PixelFormat::NV12) => {}
+            (depth, format) => {
+                let (bytes_per_pixel, red, blue) = match format {
+                    PixelFormat::RGBA => (4, 0, 2),
+                    PixelFormat::BGRA => (4, 2, 0),
+                    PixelFormat::RGB => (3, 0, 2),
+                    _ => (3, 2, 0),
+                };
+                let row_bytes = frame.width as usize * bytes_per_pixel;
+                for row in frame.data.chunks_mut(frame.stride.max(1) as usize) {
+                    let end = row_bytes.min(row.len());
+                    for pixel in row[..end].chunks_exact_mut(bytes_per_pixel) {
+                        if *depth == ColorDepth::Grayscale {
+                            // ITU-R BT.601 luma in integer math
+                            let luma = ((pixel[red] as u32 * 77 + pixel[1] as u32 * 150 + pixel[blue] as u32 * 29) >> 8) as u8;
+                            pixel[red] = luma;
+                            pixel[1] = luma;
+                            pixel[blue] = luma;
+                        } else {
+                            pixel[red] &= 0xe0;
+                            pixel[1] &= 0xe0;
+                            pixel[blue] &= 0xc0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Token bucket metering bytes at a fixed rate
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Refill rate in bytes per second
+    rate: f64,
+    /// Most tokens the bucket holds
+    capacity: f64,
+    /// Tokens available; negative while reservations wait for the refill
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket refilling at `kbps`
+    pub fn new(kbps: u32, now: Instant) -> Self {
+        let rate = bytes_per_second(kbps);
+        let capacity = rate * BURST_SECONDS;
+        Self { rate, capacity, tokens: capacity, updated: now }
+    }
+
+    /// Change the refill rate, keeping what the bucket holds up to the new
+    /// capacity
+    pub fn set_rate(&mut self, kbps: u32, now: Instant) {
+        self.refill(now);
+        self.rate = bytes_per_second(kbps);
+        self.capacity = self.rate * BURST_SECONDS;
+        self.tokens = self.tokens.min(self.capacity);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = self.updated.max(now);
+    }
+
+    /// Take `bytes` out of the bucket, returning how long to wait before
+    /// sending them. Reservations queue behind each other, so a frame larger
+    /// than the bucket still goes out, only later.
+    pub fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Tokens available at `now`
+    pub fn available(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+}
+
+fn bytes_per_second(kbps: u32) -> f64 {
+    kbps.max(1) as f64 * 1000.0 / 8.0
+}
+
+/// Bytes sent over the last few seconds
+#[derive(Debug, Default)]
+struct ThroughputMeter {
+    sent: VecDeque<(Instant, usize)>,
+    total: usize,
+}
+
+impl ThroughputMeter {
+    fn record(&mut self, bytes: usize, now: Instant) {
+        self.sent.push_back((now, bytes));
+        self.total += bytes;
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, bytes)) = self.sent.front() {
+            if now.saturating_duration_since(at) <= THROUGHPUT_WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+            self.total -= bytes;
+        }
+    }
+
+    fn kbps(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+        (self.total as f64 * 8.0 / 1000.0 / THROUGHPUT_WINDOW.as_secs_f64()).round() as u32
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    /// Cap asked for by the technician
+    requested: Option<u32>,
+    /// Organization-wide ceiling from the server's policy
+    ceiling: Option<u32>,
+    bucket: Option<TokenBucket>,
+    meter: ThroughputMeter,
+}
+
+impl LimiterState {
+    fn effective(&self) -> Option<u32> {
+        let cap = match (self.requested, self.ceiling) {
+            (Some(requested), Some(ceiling)) => Some(requested.min(ceiling)),
+            (cap, None) | (None, cap) => cap,
+        };
+        cap.map(|kbps| kbps.max(MIN_BANDWIDTH_KBPS))
+    }
+
+    fn update_bucket(&mut self, now: Instant) -> Option<u32> {
+        let cap = self.effective();
+        match (cap, self.bucket.as_mut()) {
+            (Some(kbps), Some(bucket)) => bucket.set_rate(kbps, now),
+            (Some(kbps), None) => self.bucket = Some(TokenBucket::new(kbps, now)),
+            (None, _) => self.bucket = None,
+        }
+        cap
+    }
+}
+
+/// Holds a session's outgoing frames to its bandwidth cap and measures what
+/// actually went out
+#[derive(Debug, Default)]
+pub struct BandwidthLimiter {
+    state: parking_lot::Mutex<LimiterState>,
+}
+
+impl BandwidthLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the technician's cap, or lift it with `None`; returns the cap now
+    /// in force
+    pub fn set_cap(&self, kbps: Option<u32>) -> Option<u32> {
+        let mut state = self.state.lock();
+        state.requested = kbps;
+        state.update_bucket(Instant::now())
+    }
+
+    /// Set the policy ceiling no cap may exceed; returns the cap now in force
+    pub fn set_ceiling(&self, kbps: Option<u32>) -> Option<u32> {
+        let mut state = self.state.lock();
+        state.ceiling = kbps;
+        state.update_bucket(Instant::now())
+    }
+
+    /// Cap in force, the lower of the technician's and the policy's
+    pub fn cap_kbps(&self) -> Option<u32> {
+        self.state.lock().effective()
+    }
+
+    /// Wait until `bytes` fit under the cap, then count them as sent
+    pub async fn admit(&self, bytes: usize) {
+        let wait = self.state.lock().bucket.as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.reserve(bytes, Instant::now()));
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        self.state.lock().meter.record(bytes, Instant::now());
+    }
+
+    /// Measured send rate over the last two seconds
+    pub fn throughput_kbps(&self) -> u32 {
+        self.state.lock().meter.kbps(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_holds_the_rate() {
+        let start = Instant::now();
+        // 800 kbps is 100 000 bytes a second, with 25 000 bytes of burst
+        let mut bucket = TokenBucket::new(800, start);
+        assert_eq!(bucket.reserve(20_000, start), Duration::ZERO);
+        assert_eq!(bucket.available(start), 5_000.0);
+
+        // Overdrawing waits for exactly the missing bytes
+        assert_eq!(bucket.reserve(15_000, start), Duration::from_millis(100));
+        // Later reservations queue behind it
+        assert_eq!(bucket.reserve(10_000, start), Duration::from_millis(200));
+
+        // The refill never exceeds the burst, however long the pause
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.available(later), 25_000.0);
+
+        // Sending as fast as allowed for ten seconds stays within burst + rate * t
+        let mut now = later;
+        let mut sent = 0;
+        while now < later + Duration::from_secs(10) {
+            now += bucket.reserve(40_000, now);
+            sent += 40_000;
+        }
+        let allowed = 25_000.0 + 100_000.0 * (now - later).as_secs_f64();
+        assert!(sent as f64 <= allowed + 1.0, "{} > {}", sent, allowed);
+    }
+
+    #[test]
+    fn test_token_bucket_rate_change() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(8000, start);
+        assert_eq!(bucket.available(start), 250_000.0);
+
+        // A lower cap shrinks the burst along with it
+        bucket.set_rate(80, start);
+        assert_eq!(bucket.available(start), 2_500.0);
+        assert_eq!(bucket.reserve(12_500, start), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_policy_ceiling_wins() {
+        let limiter = BandwidthLimiter::new();
+        assert_eq!(limiter.cap_kbps(), None);
+
+        assert_eq!(limiter.set_cap(Some(2000)), Some(2000));
+        assert_eq!(limiter.set_ceiling(Some(500)), Some(500));
+        // A cap under the ceiling stands
+        assert_eq!(limiter.set_cap(Some(300)), Some(300));
+        // Lifting the session cap falls back to the ceiling, not unlimited
+        assert_eq!(limiter.set_cap(None), Some(500));
+        assert_eq!(limiter.set_ceiling(None), None);
+        // Caps too low to carry a frame are raised to the minimum
+        assert_eq!(limiter.set_cap(Some(1)), Some(MIN_BANDWIDTH_KBPS));
+    }
+
+    #[test]
+    fn test_color_reduction() {
+        let mut frame = Frame {
+            data: vec![255, 0, 0, 255, 0x3f, 0x9f, 0x7f, 255],
+            width: 2,
+            height: 1,
+            pixel_format: PixelFormat::RGBA,
+            stride: 8,
+            timestamp: 0,
+        };
+        ColorDepth::Palette256.apply(&mut frame);
+        assert_eq!(frame.data, vec![224, 0, 0, 255, 0x20, 0x80, 0x40, 255]);
+
+        ColorDepth::Grayscale.apply(&mut frame);
+        assert_eq!(&frame.data[..4], &[67, 67, 67, 255]);
+        assert_eq!(frame.data[4], frame.data[5]);
+        assert_eq!(frame.data[5], frame.data[6]);
+    }
+
+    #[tokio::test]
+    async fn test_throughput_is_measured() {
+        let limiter = BandwidthLimiter::new();
+        limiter.admit(50_000).await;
+        limiter.admit(50_000).await;
+        // 100 kB over the two second window
+        assert_eq!(limiter.throughput_kbps(), 400);
+    }
+}
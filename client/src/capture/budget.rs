@@ -0,0 +1,212 @@
+//! CPU budget for capture and encoding
+//!
+//! The budget is a share of one core that capture and encoding may use,
+//! shared by every session the agent runs. It works as a leaky bucket:
+//! credit accrues at the budget's rate while time passes, up to a short
+//! burst, and each frame spends the time it took to capture and encode.
+//! Once the credit runs out the next frame waits until it is paid back, so
+//! frames are delayed, and at worst skipped, until the average is back
+//! under the budget. This is independent of the adaptive controller, which
+//! steers the frame rate by what the pipeline and the link can carry; the
+//! capture loop waits for whichever asks for the longer gap.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::adaptive::CaptureStats;
+
+/// Busy time frames may spend ahead of the budget, so a keyframe or a
+/// burst of changes is not held back
+const BURST: Duration = Duration::from_millis(100);
+
+/// Span utilization is measured over
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Budgets the agent accepts, in percent of one core
+pub const BUDGET_RANGE: std::ops::RangeInclusive<u32> = 5..=100;
+
+/// Paces capture to a share of one core
+#[derive(Debug)]
+pub struct CpuBudget {
+    /// Share of one core, or none for no budget
+    share: Option<f64>,
+    /// Busy time frames may still spend, in seconds; negative while in debt
+    credit: f64,
+    refilled: Instant,
+    window_start: Instant,
+    window_busy: Duration,
+    /// Utilization over the last complete window, in percent of one core
+    utilization: f64,
+    throttled: u64,
+}
+
+impl CpuBudget {
+    pub fn new(percent: Option<u32>, now: Instant) -> Self {
+        let mut budget = Self {
+            share: None,
+            credit: BURST.as_secs_f64(),
+            refilled: now,
+            window_start: now,
+            window_busy: Duration::ZERO,
+            utilization: 0.0,
+            throttled: 0,
+        };
+        budget.set_percent(percent);
+        budget
+    }
+
+    /// Change the budget; `None` lifts it
+    pub fn set_percent(&mut self, percent: Option<u32>) {
+        self.share = percent.map(|percent| percent.clamp(*BUDGET_RANGE.start(), *BUDGET_RANGE.end()) as f64 / 100.0);
+    }
+
+    pub fn percent(&self) -> Option<u32> {
+        self.share.map(|share| (share * 100.0).round() as u32)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.refilled = self.refilled.max(now);
+        if let Some(share) = self.share {
+            self.credit = (self.credit + elapsed * share).min(BURST.as_secs_f64());
+        }
+    }
+
+    /// Account for a frame that kept capture and encoding `busy`
+    pub fn record(&mut self, busy: Duration, now: Instant) {
+        self.refill(now);
+        if self.share.is_some() {
+            self.credit -= busy.as_secs_f64();
+        }
+
+        self.window_busy += busy;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= WINDOW {
+            self.utilization = self.window_busy.as_secs_f64() / elapsed.as_secs_f64() * 100.0;
+            self.window_start = now;
+            self.window_busy = Duration::ZERO;
+        }
+    }
+
+    /// How long the next frame must wait to stay within the budget; a wait
+    /// counts the frame as throttled
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        match self.share {
+            Some(share) if self.credit < 0.0 => {
+                self.throttled += 1;
+                Duration::from_secs_f64(-self.credit / share)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Capture and encode time in percent of one core. A window left open
+    /// longer than its span, as when capture stopped, counts as it stands,
+    /// so the reading falls back to zero.
+    pub fn utilization(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= WINDOW * 2 {
+            self.window_busy.as_secs_f64() / elapsed.as_secs_f64() * 100.0
+        } else {
+            self.utilization
+        }
+    }
+
+    /// Frames that waited for the budget so far
+    pub fn throttled(&self) -> u64 {
+        self.throttled
+    }
+
+    pub fn fill_stats(&self, stats: &mut CaptureStats, now: Instant) {
+        stats.cpu_percent = self.utilization(now);
+        stats.cpu_budget_percent = self.percent();
+        stats.frames_throttled = self.throttled;
+    }
+}
+
+static SHARED: OnceLock<Mutex<CpuBudget>> = OnceLock::new();
+
+/// The budget every capture loop of the agent draws from; unlimited until
+/// the agent sets one
+pub fn shared() -> &'static Mutex<CpuBudget> {
+    SHARED.get_or_init(|| Mutex::new(CpuBudget::new(None, Instant::now())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Run frames that each keep the pipeline `busy`, as often as the
+    /// budget and `interval` allow, for `span`; returns the frames run
+    fn simulate(budget: &mut CpuBudget, start: Instant, busy: Duration, interval: Duration, span: Duration) -> (u32, Instant) {
+        let mut now = start;
+        let mut frames = 0;
+        while now < start + span {
+            now += busy;
+            budget.record(busy, now);
+            frames += 1;
+            now += interval.max(budget.delay(now));
+        }
+        (frames, now)
+    }
+
+    #[test]
+    fn test_budget_holds_capture_to_its_share() {
+        let start = Instant::now();
+        // 20 ms per frame at 30 fps is 60% of a core
+        let mut budget = CpuBudget::new(Some(30), start);
+        let (frames, now) = simulate(&mut budget, start, 20 * MS, 13 * MS, Duration::from_secs(10));
+
+        // 30% of a core over 10 s is 3 s of busy time, plus the burst
+        assert!((140..=170).contains(&frames), "{} frames", frames);
+        let utilization = budget.utilization(now);
+        assert!((27.0..=33.0).contains(&utilization), "{}%", utilization);
+        assert!(budget.throttled() > 100);
+
+        let mut stats = CaptureStats::default();
+        budget.fill_stats(&mut stats, now);
+        assert_eq!(stats.cpu_budget_percent, Some(30));
+        assert_eq!(stats.frames_throttled, budget.throttled());
+    }
+
+    #[test]
+    fn test_frames_within_budget_are_not_delayed() {
+        let start = Instant::now();
+        let mut budget = CpuBudget::new(Some(50), start);
+        // 5 ms per frame at 30 fps is 15% of a core
+        let (frames, now) = simulate(&mut budget, start, 5 * MS, 28 * MS, Duration::from_secs(3));
+        assert_eq!(budget.throttled(), 0);
+        assert!(frames >= 90);
+        assert!((14.0..=16.0).contains(&budget.utilization(now)));
+
+        // A single slow keyframe fits the burst
+        let keyframe = now + 80 * MS;
+        budget.record(80 * MS, keyframe);
+        assert_eq!(budget.delay(keyframe), Duration::ZERO);
+        // Two at once do not; the overdraft is paid back at the budget's rate
+        budget.record(80 * MS, keyframe);
+        let delay = budget.delay(keyframe);
+        assert!(delay > 119 * MS && delay < 121 * MS, "{:?}", delay);
+    }
+
+    #[test]
+    fn test_no_budget_only_measures() {
+        let start = Instant::now();
+        let mut budget = CpuBudget::new(None, start);
+        let (_, now) = simulate(&mut budget, start, 30 * MS, 3 * MS, Duration::from_secs(2));
+        assert_eq!(budget.throttled(), 0);
+        assert_eq!(budget.percent(), None);
+        assert!(budget.utilization(now) > 85.0);
+
+        // Once capture stops the reading falls off
+        assert!(budget.utilization(now + Duration::from_secs(10)) < 10.0);
+
+        budget.set_percent(Some(1));
+        assert_eq!(budget.percent(), Some(*BUDGET_RANGE.start()));
+    }
+}
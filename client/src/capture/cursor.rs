@@ -0,0 +1,353 @@
+//! Cursor position and shape, streamed apart from video frames
+//!
+//! Capturers whose frames leave the cursor out report where it is on every
+//! tick of the capture loop. The tracker turns those samples into small
+//! `CursorUpdate` messages: nothing while the cursor stands still, and the
+//! shape only when it changed, so the viewer can draw the cursor itself at
+//! its own pace instead of waiting for the next encoded frame.
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::{GhostLinkError, Result};
+
+/// Cursor image as a platform reports it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CursorImage {
+    /// Changes whenever the shape does; equal serials mean equal shapes
+    pub serial: u64,
+    pub width: u32,
+    pub height: u32,
+    pub hotspot_x: u32,
+    pub hotspot_y: u32,
+    /// Straight (not premultiplied) RGBA pixels
+    pub rgba: Vec<u8>,
+}
+
+impl CursorImage {
+    /// Image from premultiplied ARGB pixels, as X11 XFixes hands them out
+    pub fn from_argb(serial: u64, width: u32, height: u32, hotspot_x: u32, hotspot_y: u32, argb: &[u32]) -> Self {
+        let rgba = argb
+            .iter()
+            .flat_map(|&pixel| {
+                let alpha = pixel >> 24;
+                let channel = |shift: u32| {
+                    let value = (pixel >> shift) & 0xff;
+                    match alpha {
+                        0 => 0,
+                        alpha => (value * 255 / alpha).min(255) as u8,
+                    }
+                };
+                [channel(16), channel(8), channel(0), alpha as u8]
+            })
+            .collect();
+        Self { serial, width, height, hotspot_x, hotspot_y, rgba }
+    }
+}
+
+/// What a capturer saw of the cursor on one tick
+#[derive(Debug, Clone, PartialEq)]
+pub struct CursorSample {
+    /// Position of the hotspot in desktop coordinates
+    pub x: i32,
+    pub y: i32,
+    pub visible: bool,
+    /// Current shape, if the platform can read it
+    pub image: Option<CursorImage>,
+}
+
+/// Cursor shape as it travels to the viewer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorShape {
+    pub serial: u64,
+    pub width: u32,
+    pub height: u32,
+    pub hotspot_x: u32,
+    pub hotspot_y: u32,
+    /// Base64 PNG
+    pub image: String,
+}
+
+impl CursorShape {
+    /// Encode `image` as a PNG for the viewer
+    pub fn encode(image: &CursorImage) -> Result<Self> {
+        let mut png_data = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png_data, image.width, image.height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+
+            let mut writer = encoder.write_header()
+                .map_err(|e| GhostLinkError::Other(format!("PNG encoder creation failed: {}", e)))?;
+            writer.write_image_data(&image.rgba)
+                .map_err(|e| GhostLinkError::Other(format!("PNG encoding failed: {}", e)))?;
+        }
+
+        Ok(Self {
+            serial: image.serial,
+            width: image.width,
+            height: image.height,
+            hotspot_x: image.hotspot_x,
+            hotspot_y: image.hotspot_y,
+            image: base64::engine::general_purpose::STANDARD.encode(png_data),
+        })
+    }
+}
+
+/// Payload of a `CursorUpdate` message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorUpdate {
+    pub x: i32,
+    pub y: i32,
+    pub visible: bool,
+    /// Only sent when the shape changed; viewers keep the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shape: Option<CursorShape>,
+}
+
+/// Turns cursor samples into updates worth sending
+#[derive(Debug, Default)]
+pub struct CursorTracker {
+    /// Position and visibility the viewer last got
+    position: Option<(i32, i32, bool)>,
+    /// Serial of the shape the viewer last got
+    shape_serial: Option<u64>,
+}
+
+impl CursorTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The update for `sample`, or `None` if the viewer already has it
+    pub fn update(&mut self, sample: CursorSample) -> Option<CursorUpdate> {
+        let shape = match sample.image {
+            Some(image) if sample.visible && self.shape_serial != Some(image.serial) => {
+                match CursorShape::encode(&image) {
+                    Ok(shape) => {
+                        self.shape_serial = Some(image.serial);
+                        Some(shape)
+                    }
+                    Err(e) => {
+                        // Tried again with the next sample
+                        warn!("Failed to encode cursor shape: {}", e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let position = (sample.x, sample.y, sample.visible);
+        if shape.is_none() && self.position == Some(position) {
+            return None;
+        }
+        self.position = Some(position);
+        Some(CursorUpdate { x: sample.x, y: sample.y, visible: sample.visible, shape })
+    }
+
+    /// Send the position and shape again, e.g. for a viewer that just
+    /// joined or turned cursor streaming back on
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// The system cursor, read with `GetCursorInfo`
+#[cfg(windows)]
+pub fn system_cursor() -> Option<CursorSample> {
+    windows_cursor::sample()
+}
+
+#[cfg(windows)]
+mod windows_cursor {
+    use super::{CursorImage, CursorSample};
+    use windows::Win32::Foundation::HWND;
+    use windows::Win32::Graphics::Gdi::{
+        DeleteObject, GetDC, GetDIBits, GetObjectW, ReleaseDC, BITMAP, BITMAPINFO, BITMAPINFOHEADER,
+        BI_RGB, DIB_RGB_COLORS, HBITMAP,
+    };
+    use windows::Win32::UI::WindowsAndMessaging::{
+        GetCursorInfo, GetIconInfo, CURSORINFO, CURSOR_SHOWING, HCURSOR, HICON, ICONINFO,
+    };
+
+    pub fn sample() -> Option<CursorSample> {
+        let mut info = CURSORINFO {
+            cbSize: std::mem::size_of::<CURSORINFO>() as u32,
+            ..Default::default()
+        };
+        unsafe { GetCursorInfo(&mut info) }.ok()?;
+
+        let visible = info.flags.0 & CURSOR_SHOWING.0 != 0;
+        let image = if visible && !info.hCursor.is_invalid() {
+            cursor_image(info.hCursor)
+        } else {
+            None
+        };
+        Some(CursorSample { x: info.ptScreenPos.x, y: info.ptScreenPos.y, visible, image })
+    }
+
+    /// The cursor's pixels; its handle stays the same as long as the shape does
+    fn cursor_image(cursor: HCURSOR) -> Option<CursorImage> {
+        let mut icon = ICONINFO::default();
+        unsafe { GetIconInfo(HICON(cursor.0), &mut icon) }.ok()?;
+
+        let pixels = if icon.hbmColor.is_invalid() {
+            // Monochrome cursors stack their AND and XOR masks in one bitmap
+            read_bitmap(icon.hbmMask).map(|(width, height, masks)| (width, height / 2, monochrome(width, height / 2, &masks)))
+        } else {
+            read_bitmap(icon.hbmColor).map(|(width, height, bgra)| (width, height, color(bgra)))
+        };
+        unsafe {
+            let _ = DeleteObject(icon.hbmMask);
+            if !icon.hbmColor.is_invalid() {
+                let _ = DeleteObject(icon.hbmColor);
+            }
+        }
+
+        let (width, height, rgba) = pixels?;
+        Some(CursorImage {
+            serial: cursor.0 as u64,
+            width,
+            height,
+            hotspot_x: icon.xHotspot,
+            hotspot_y: icon.yHotspot,
+            rgba,
+        })
+    }
+
+    /// A bitmap's pixels as top-down 32-bit BGRA
+    fn read_bitmap(bitmap: HBITMAP) -> Option<(u32, u32, Vec<u8>)> {
+        let mut header = BITMAP::default();
+        let read = unsafe {
+            GetObjectW(bitmap, std::mem::size_of::<BITMAP>() as i32, Some(&mut header as *mut BITMAP as *mut _))
+        };
+        if read == 0 || header.bmWidth <= 0 || header.bmHeight <= 0 {
+            return None;
+        }
+        let (width, height) = (header.bmWidth as u32, header.bmHeight as u32);
+
+        let mut info = BITMAPINFO {
+            bmiHeader: BITMAPINFOHEADER {
+                biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+                biWidth: header.bmWidth,
+                // Negative height asks for top-down rows
+                biHeight: -header.bmHeight,
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+        let lines = unsafe {
+            let screen = GetDC(HWND(0));
+            let lines = GetDIBits(screen, bitmap, 0, height, Some(pixels.as_mut_ptr() as *mut _), &mut info, DIB_RGB_COLORS);
+            ReleaseDC(HWND(0), screen);
+            lines
+        };
+        (lines == height as i32).then_some((width, height, pixels))
+    }
+
+    fn color(mut bgra: Vec<u8>) -> Vec<u8> {
+        // Old-style color cursors leave the alpha channel empty
+        let opaque = bgra.chunks_exact(4).all(|pixel| pixel[3] == 0);
+        for pixel in bgra.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+            if opaque {
+                pixel[3] = 255;
+            }
+        }
+        bgra
+    }
+
+    /// Pixels of a monochrome cursor; screen-inverting pixels come out black
+    fn monochrome(width: u32, height: u32, masks: &[u8]) -> Vec<u8> {
+        let half = (width * height * 4) as usize;
+        let (and_mask, xor_mask) = masks.split_at(half);
+        and_mask
+            .chunks_exact(4)
+            .zip(xor_mask.chunks_exact(4))
+            .flat_map(|(and, xor)| match (and[0] != 0, xor[0] != 0) {
+                (false, false) => [0, 0, 0, 255],
+                (false, true) => [255, 255, 255, 255],
+                (true, false) => [0, 0, 0, 0],
+                (true, true) => [0, 0, 0, 255],
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(x: i32, y: i32, serial: u64) -> CursorSample {
+        CursorSample {
+            x,
+            y,
+            visible: true,
+            image: Some(CursorImage {
+                serial,
+                width: 2,
+                height: 2,
+                hotspot_x: 1,
+                hotspot_y: 0,
+                rgba: vec![255; 16],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_shape_is_only_sent_when_it_changes() {
+        let mut tracker = CursorTracker::new();
+
+        let first = tracker.update(sample(10, 20, 1)).unwrap();
+        assert_eq!(first.shape.as_ref().map(|shape| (shape.serial, shape.hotspot_x)), Some((1, 1)));
+
+        // Moving keeps the shape the viewer already has
+        let moved = tracker.update(sample(11, 20, 1)).unwrap();
+        assert_eq!((moved.x, moved.y, moved.shape), (11, 20, None));
+
+        // Nothing changed, nothing to send
+        assert_eq!(tracker.update(sample(11, 20, 1)), None);
+
+        // A new shape goes out even if the cursor stands still
+        let reshaped = tracker.update(sample(11, 20, 2)).unwrap();
+        assert_eq!(reshaped.shape.map(|shape| shape.serial), Some(2));
+
+        // Hiding the cursor is an update, but carries no shape
+        let hidden = tracker.update(CursorSample { visible: false, ..sample(11, 20, 3) }).unwrap();
+        assert!(!hidden.visible && hidden.shape.is_none());
+
+        // A fresh viewer gets everything again
+        tracker.reset();
+        assert!(tracker.update(sample(11, 20, 2)).unwrap().shape.is_some());
+    }
+
+    #[test]
+    fn test_cursor_update_serialization() {
+        let position = CursorUpdate { x: -5, y: 300, visible: true, shape: None };
+        let json = serde_json::to_value(&position).unwrap();
+        assert_eq!(json, serde_json::json!({ "x": -5, "y": 300, "visible": true }));
+        assert_eq!(serde_json::from_value::<CursorUpdate>(json).unwrap(), position);
+
+        let with_shape = CursorTracker::new().update(sample(0, 0, 7)).unwrap();
+        let json = serde_json::to_value(&with_shape).unwrap();
+        assert_eq!(json["shape"]["serial"], 7);
+        let png = base64::engine::general_purpose::STANDARD
+            .decode(json["shape"]["image"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+        assert_eq!(serde_json::from_value::<CursorUpdate>(json).unwrap(), with_shape);
+    }
+
+    #[test]
+    fn test_premultiplied_argb_is_straightened() {
+        // Half-transparent white, a fully transparent pixel and opaque red
+        let image = CursorImage::from_argb(1, 3, 1, 0, 0, &[0x8080_8080, 0x0000_0000, 0xffff_0000]);
+        assert_eq!(image.rgba, vec![255, 255, 255, 128, 0, 0, 0, 0, 255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Accumulation of damage reported by the windowing system
+//!
+//! XDamage reports every changed rectangle of the root window, often many
+//! small and overlapping ones per frame. The accumulator clips them to the
+//! screen and merges those that overlap or touch, so the capturer grabs
+//! each changed pixel once. When the damage gets fragmented or covers most
+//! of the screen, one full-screen grab is cheaper than many small ones.
+
+use super::frame_diff::DirtyRect;
+
+/// Rectangles kept apart before the whole screen is grabbed instead
+const MAX_RECTS: usize = 32;
+
+/// Share of the screen above which damage becomes a full grab
+const FULL_GRAB_COVERAGE: f64 = 0.6;
+
+/// Changed screen regions since the last grab
+#[derive(Debug, Clone)]
+pub struct DamageAccumulator {
+    width: u32,
+    height: u32,
+    rects: Vec<DirtyRect>,
+    full: bool,
+}
+
+impl DamageAccumulator {
+    /// Accumulator for a `width` x `height` screen; the first grab is full
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, rects: Vec::new(), full: true }
+    }
+
+    /// Record a damaged rectangle in screen coordinates
+    pub fn add(&mut self, x: i32, y: i32, width: u32, height: u32) {
+        if self.full {
+            return;
+        }
+        let Some(rect) = self.clip(x, y, width, height) else {
+            return;
+        };
+
+        // Absorb everything the new rectangle overlaps or touches, until
+        // nothing more does
+        let mut merged = rect;
+        loop {
+            let before = self.rects.len();
+            self.rects.retain(|existing| {
+                if touches(existing, &merged) {
+                    merged = union(existing, &merged);
+                    false
+                } else {
+                    true
+                }
+            });
+            if self.rects.len() == before {
+                break;
+            }
+        }
+        self.rects.push(merged);
+
+        if self.rects.len() > MAX_RECTS || self.coverage() > FULL_GRAB_COVERAGE {
+            self.mark_full();
+        }
+    }
+
+    /// Grab the whole screen next time, e.g. after events were lost
+    pub fn mark_full(&mut self) {
+        self.full = true;
+        self.rects.clear();
+    }
+
+    /// The screen changed size; everything is new
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.mark_full();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.full && self.rects.is_empty()
+    }
+
+    /// Regions to grab, leaving the accumulator empty
+    pub fn take(&mut self) -> Vec<DirtyRect> {
+        if std::mem::take(&mut self.full) {
+            self.rects.clear();
+            return vec![DirtyRect { x: 0, y: 0, width: self.width, height: self.height }];
+        }
+        std::mem::take(&mut self.rects)
+    }
+
+    fn clip(&self, x: i32, y: i32, width: u32, height: u32) -> Option<DirtyRect> {
+        let left = x.max(0) as i64;
+        let top = y.max(0) as i64;
+        let right = (x as i64 + width as i64).min(self.width as i64);
+        let bottom = (y as i64 + height as i64).min(self.height as i64);
+        (right > left && bottom > top).then(|| DirtyRect {
+            x: left as u32,
+            y: top as u32,
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        })
+    }
+
+    /// Share of the screen covered; merged rectangles never overlap
+    fn coverage(&self) -> f64 {
+        let screen = self.width as f64 * self.height as f64;
+        if screen == 0.0 {
+            return 0.0;
+        }
+        let damaged: f64 = self.rects.iter().map(|rect| rect.width as f64 * rect.height as f64).sum();
+        damaged / screen
+    }
+}
+
+/// Whether two rectangles overlap or share an edge
+fn touches(a: &DirtyRect, b: &DirtyRect) -> bool {
+    a.x <= b.x + b.width && b.x <= a.x + a.width && a.y <= b.y + b.height && b.y <= a.y + a.height
+}
+
+fn union(a: &DirtyRect, b: &DirtyRect) -> DirtyRect {
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    DirtyRect {
+        x,
+        y,
+        width: (a.x + a.width).max(b.x + b.width) - x,
+        height: (a.y + a.height).max(b.y + b.height) - y,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> DirtyRect {
+        DirtyRect { x, y, width, height }
+    }
+
+    /// Accumulator past its initial full grab
+    fn accumulator() -> DamageAccumulator {
+        let mut damage = DamageAccumulator::new(1000, 1000);
+        assert_eq!(damage.take(), vec![rect(0, 0, 1000, 1000)]);
+        damage
+    }
+
+    #[test]
+    fn test_overlapping_and_touching_damage_merges() {
+        let mut damage = accumulator();
+        assert!(damage.is_empty());
+
+        damage.add(10, 10, 20, 20);
+        damage.add(20, 20, 20, 20);
+        damage.add(500, 500, 10, 10);
+        // Shares an edge with the first rectangle
+        damage.add(40, 10, 5, 5);
+        assert_eq!(damage.take(), vec![rect(500, 500, 10, 10), rect(10, 10, 35, 30)]);
+        assert!(damage.is_empty());
+        assert!(damage.take().is_empty());
+    }
+
+    #[test]
+    fn test_merging_chains_through_earlier_rectangles() {
+        let mut damage = accumulator();
+        damage.add(0, 0, 10, 10);
+        damage.add(100, 0, 10, 10);
+        // Bridges both, which then become one
+        damage.add(5, 0, 100, 5);
+        assert_eq!(damage.take(), vec![rect(0, 0, 110, 10)]);
+    }
+
+    #[test]
+    fn test_damage_is_clipped_to_the_screen() {
+        let mut damage = accumulator();
+        damage.add(-5, 990, 20, 50);
+        damage.add(2000, 0, 10, 10);
+        damage.add(0, 0, 0, 10);
+        assert_eq!(damage.take(), vec![rect(0, 990, 15, 10)]);
+    }
+
+    #[test]
+    fn test_fragmented_or_large_damage_becomes_a_full_grab() {
+        let mut damage = accumulator();
+        for i in 0..=MAX_RECTS as i32 {
+            damage.add(i * 20, 0, 10, 10);
+        }
+        assert_eq!(damage.take(), vec![rect(0, 0, 1000, 1000)]);
+
+        damage.add(0, 0, 1000, 700);
+        assert_eq!(damage.take(), vec![rect(0, 0, 1000, 1000)]);
+
+        damage.add(0, 0, 1000, 500);
+        assert_eq!(damage.take(), vec![rect(0, 0, 1000, 500)]);
+    }
+
+    #[test]
+    fn test_resize_grabs_the_new_screen_in_full() {
+        let mut damage = accumulator();
+        damage.add(0, 0, 10, 10);
+        damage.resize(1920, 1080);
+        // Damage until the next grab is covered by it
+        damage.add(0, 0, 10, 10);
+        assert_eq!(damage.take(), vec![rect(0, 0, 1920, 1080)]);
+        damage.add(1900, 1000, 100, 100);
+        assert_eq!(damage.take(), vec![rect(1900, 1000, 20, 80)]);
+    }
+}
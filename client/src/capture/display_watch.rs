@@ -0,0 +1,160 @@
+//! Display configuration change notifications from the OS
+//!
+//! Windows and macOS announce monitor hot-plugs and resolution changes
+//! process-wide, so one listener per process bumps a counter that every
+//! capture loop's watcher compares against. The X11 and Wayland capturers
+//! see changes on their own connections instead and report them through
+//! `ScreenCapturer::take_display_change`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Display configuration changes the OS reported since the process started
+static CHANGES: AtomicU64 = AtomicU64::new(0);
+
+/// Notices display changes reported since it last looked
+#[derive(Debug)]
+pub struct DisplayWatcher {
+    seen: u64,
+}
+
+impl DisplayWatcher {
+    /// Start listening, once per process, where the OS broadcasts changes
+    pub fn start() -> Self {
+        #[cfg(any(target_os = "windows", target_os = "macos"))]
+        {
+            static LISTENER: std::sync::Once = std::sync::Once::new();
+            LISTENER.call_once(platform::listen);
+        }
+        Self { seen: CHANGES.load(Ordering::SeqCst) }
+    }
+
+    /// Whether the display configuration changed since the last call
+    pub fn take_changed(&mut self) -> bool {
+        let changes = CHANGES.load(Ordering::SeqCst);
+        std::mem::replace(&mut self.seen, changes) != changes
+    }
+}
+
+/// Count a change the OS reported
+#[cfg(any(target_os = "windows", target_os = "macos", test))]
+fn notify() {
+    CHANGES.fetch_add(1, Ordering::SeqCst);
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use tracing::{debug, warn};
+    use windows::core::w;
+    use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::UI::WindowsAndMessaging::{
+        CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW, TranslateMessage, HMENU,
+        MSG, WINDOW_EX_STYLE, WINDOW_STYLE, WM_DISPLAYCHANGE, WNDCLASSW,
+    };
+
+    /// WM_DISPLAYCHANGE is broadcast to top-level windows, so a hidden one
+    /// on its own thread receives it
+    pub fn listen() {
+        let spawned = std::thread::Builder::new()
+            .name("display-watch".to_string())
+            .spawn(|| unsafe { run_message_loop() });
+        if let Err(e) = spawned {
+            warn!("Failed to start display change listener: {}", e);
+        }
+    }
+
+    unsafe fn run_message_loop() {
+        let class_name = w!("GhostLinkDisplayWatch");
+        let class = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            lpszClassName: class_name,
+            ..Default::default()
+        };
+        if RegisterClassW(&class) == 0 {
+            warn!("Failed to register display change window class");
+            return;
+        }
+
+        let hwnd = CreateWindowExW(
+            WINDOW_EX_STYLE::default(),
+            class_name,
+            w!(""),
+            WINDOW_STYLE::default(),
+            0,
+            0,
+            0,
+            0,
+            HWND::default(),
+            HMENU::default(),
+            HINSTANCE::default(),
+            None,
+        );
+        if hwnd.0 == 0 {
+            warn!("Failed to create display change window");
+            return;
+        }
+
+        let mut msg = MSG::default();
+        // -1 is an error, 0 WM_QUIT
+        while GetMessageW(&mut msg, HWND::default(), 0, 0).0 > 0 {
+            let _ = TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+    }
+
+    unsafe extern "system" fn window_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        if msg == WM_DISPLAYCHANGE {
+            debug!("Display configuration changed (WM_DISPLAYCHANGE)");
+            super::notify();
+        }
+        DefWindowProcW(hwnd, msg, wparam, lparam)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::c_void;
+    use tracing::{debug, warn};
+
+    /// `kCGDisplayBeginConfigurationFlag`: announced before the change,
+    /// which is counted once it is done
+    const BEGIN_CONFIGURATION: u32 = 1;
+
+    type ReconfigurationCallback = extern "C" fn(display: u32, flags: u32, user_info: *mut c_void);
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGDisplayRegisterReconfigurationCallback(callback: ReconfigurationCallback, user_info: *mut c_void) -> i32;
+    }
+
+    extern "C" fn reconfigured(display: u32, flags: u32, _user_info: *mut c_void) {
+        if flags & BEGIN_CONFIGURATION == 0 {
+            debug!("Display {} reconfigured (flags {:#x})", display, flags);
+            super::notify();
+        }
+    }
+
+    pub fn listen() {
+        let result = unsafe { CGDisplayRegisterReconfigurationCallback(reconfigured, std::ptr::null_mut()) };
+        if result != 0 {
+            warn!("Failed to register display reconfiguration callback: {}", result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchers_see_each_change_once() {
+        let mut first = DisplayWatcher::start();
+        let mut second = DisplayWatcher::start();
+        assert!(!first.take_changed());
+
+        notify();
+        assert!(first.take_changed());
+        assert!(!first.take_changed());
+        // Every capture loop learns about the change
+        assert!(second.take_changed());
+    }
+}
@@ -0,0 +1,679 @@
+//! Display selection for multi-monitor sessions
+//!
+//! `DisplaySwitcher` sits between the capture loop and the capturer: it
+//! switches the captured display between frames, composites all displays
+//! into one virtual desktop on request, falls back to the primary display
+//! when the selected one is unplugged, and re-initializes the encoder when
+//! the captured resolution changes.
+//!
+//! Display changes are noticed three ways: the OS or the capturer reports
+//! them, the display list differs when re-read, or a frame arrives at a
+//! size the viewer wasn't told about. Docking fires several changes in a
+//! row, so frames are held back until the changes settle. The capturer
+//! then re-reads its geometry, and the viewer learns the new size before
+//! the first frame at that size.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::error::{CaptureError, GhostLinkError, Result};
+
+use super::frame_diff::DirtyRect;
+#[cfg(target_os = "linux")]
+use super::wayland::dmabuf::DmaBufFrame;
+use super::display_watch::DisplayWatcher;
+use super::{DisplayInfo, Frame, PixelFormat, ScreenCapturer, VideoEncoder};
+
+/// How often the display list is re-read to notice hot-plugged monitors
+const HOTPLUG_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Quiet time after the last display change before the new layout is used
+const CHANGE_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Longest a burst of display changes holds frames back
+const CHANGE_MAX_HOLD: Duration = Duration::from_secs(3);
+
+/// What the capture loop captures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum CaptureTarget {
+    /// One display, by id
+    Display { display_id: u32 },
+    /// All displays composited into one frame at their desktop positions
+    AllDisplays,
+}
+
+/// Change the viewer has to know about
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisplayEvent {
+    /// The captured area changed; frames now have this size
+    Switched {
+        target: CaptureTarget,
+        width: u32,
+        height: u32,
+        /// The selected display disappeared and the primary took its place
+        fallback: bool,
+    },
+    /// Displays were added, removed or rearranged
+    ListChanged {
+        displays: Vec<DisplayInfo>,
+        current: CaptureTarget,
+    },
+}
+
+/// Waits out a burst of display change notifications
+#[derive(Debug)]
+struct ChangeDebounce {
+    settle_time: Duration,
+    max_hold: Duration,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl ChangeDebounce {
+    fn new(settle_time: Duration, max_hold: Duration) -> Self {
+        Self { settle_time, max_hold, first: None, last: None }
+    }
+
+    fn signal(&mut self, now: Instant) {
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    fn pending(&self) -> bool {
+        self.last.is_some()
+    }
+
+    /// Whether a pending change is over: quiet long enough, or held back
+    /// for too long already
+    fn settled(&self, now: Instant) -> bool {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => {
+                now.duration_since(last) >= self.settle_time || now.duration_since(first) >= self.max_hold
+            }
+            _ => false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.first = None;
+        self.last = None;
+    }
+}
+
+/// Tracks the capture target and the display list for one capture loop
+#[derive(Debug)]
+pub struct DisplaySwitcher {
+    displays: Vec<DisplayInfo>,
+    target: CaptureTarget,
+    /// Frame size the encoder was last initialized for
+    encoded_size: Option<(u32, u32)>,
+    /// Frame size the viewer was last sent frames at
+    frame_size: Option<(u32, u32)>,
+    last_check: Instant,
+    watcher: DisplayWatcher,
+    debounce: ChangeDebounce,
+}
+
+impl DisplaySwitcher {
+    /// Start out on whatever display the capturer is already capturing
+    pub fn new<C: ScreenCapturer + ?Sized>(capturer: &C) -> Self {
+        let displays = capturer.get_display_info();
+        let display_id = primary_display(&displays).map(|display| display.id).unwrap_or(0);
+
+        Self {
+            displays,
+            target: CaptureTarget::Display { display_id },
+            encoded_size: None,
+            frame_size: None,
+            last_check: Instant::now(),
+            watcher: DisplayWatcher::start(),
+            debounce: ChangeDebounce::new(CHANGE_SETTLE_TIME, CHANGE_MAX_HOLD),
+        }
+    }
+
+    pub fn displays(&self) -> &[DisplayInfo] {
+        &self.displays
+    }
+
+    pub fn target(&self) -> CaptureTarget {
+        self.target
+    }
+
+    /// Record the size the encoder was initialized for outside the switcher
+    pub fn set_encoded_size(&mut self, width: u32, height: u32) {
+        self.encoded_size = Some((width, height));
+    }
+
+    /// Switch to `target`, taking effect with the next captured frame
+    pub fn select<C: ScreenCapturer + ?Sized>(&mut self, capturer: &mut C, target: CaptureTarget) -> Result<DisplayEvent> {
+        let (width, height) = match target {
+            CaptureTarget::Display { display_id } => {
+                if !self.displays.iter().any(|display| display.id == display_id) {
+                    return Err(CaptureError::DisplayNotFound { display_id }.into());
+                }
+                capturer.select_display(display_id)?;
+                capturer.get_resolution()
+            }
+            CaptureTarget::AllDisplays => {
+                let (_, _, width, height) = desktop_bounds(&self.displays)
+                    .ok_or(GhostLinkError::Capture(CaptureError::NotInitialized))?;
+                (width, height)
+            }
+        };
+
+        info!("Capture target switched to {:?} ({}x{})", target, width, height);
+        self.target = target;
+        // Frames change size with the target; that needs no settling
+        self.frame_size = None;
+        Ok(DisplayEvent::Switched { target, width, height, fallback: false })
+    }
+
+    /// Re-read the display list and fall back to the primary display if the
+    /// selected one is gone
+    pub fn refresh<C: ScreenCapturer + ?Sized>(&mut self, capturer: &mut C) -> Result<Vec<DisplayEvent>> {
+        self.last_check = Instant::now();
+        let displays = capturer.get_display_info();
+        if displays == self.displays {
+            return Ok(Vec::new());
+        }
+
+        let old_bounds = desktop_bounds(&self.displays);
+        self.displays = displays;
+        let mut events = Vec::new();
+
+        match self.target {
+            CaptureTarget::Display { display_id } if !self.displays.iter().any(|d| d.id == display_id) => {
+                let Some(primary) = primary_display(&self.displays).map(|display| display.id) else {
+                    return Err(CaptureError::DisplayNotFound { display_id }.into());
+                };
+                warn!("Display {} disappeared, falling back to display {}", display_id, primary);
+                if let DisplayEvent::Switched { target, width, height, .. } =
+                    self.select(capturer, CaptureTarget::Display { display_id: primary })?
+                {
+                    events.push(DisplayEvent::Switched { target, width, height, fallback: true });
+                }
+            }
+            CaptureTarget::AllDisplays if desktop_bounds(&self.displays) != old_bounds => {
+                events.push(self.select(capturer, CaptureTarget::AllDisplays)?);
+            }
+            _ => {}
+        }
+
+        events.insert(0, DisplayEvent::ListChanged {
+            displays: self.displays.clone(),
+            current: self.target,
+        });
+        Ok(events)
+    }
+
+    /// Point a replacement capturer at the current target, or at the
+    /// primary display if the target went away with the old capturer
+    pub fn reattach<C: ScreenCapturer + ?Sized>(&mut self, capturer: &mut C) -> Result<Vec<DisplayEvent>> {
+        let events = self.refresh(capturer)?;
+        let switched = events.iter().any(|event| matches!(event, DisplayEvent::Switched { .. }));
+        if let (CaptureTarget::Display { display_id }, false) = (self.target, switched) {
+            // A new capturer starts out on its own default display
+            capturer.select_display(display_id)?;
+        }
+        Ok(events)
+    }
+
+    /// Capture the current target, checking for hot-plugged displays first
+    /// when a check is due. No frame comes back while a display change
+    /// settles, nor one at a size the viewer hasn't been told about yet.
+    pub async fn capture<C: ScreenCapturer + ?Sized>(&mut self, capturer: &mut C) -> Result<(Option<Frame>, Vec<DisplayEvent>)> {
+        let now = Instant::now();
+        // Both are asked, so neither reports the same change twice
+        if self.watcher.take_changed() | capturer.take_display_change() {
+            self.debounce.signal(now);
+        }
+
+        let just_settled = self.debounce.pending();
+        let mut events = if just_settled {
+            if !self.debounce.settled(now) {
+                return Ok((None, Vec::new()));
+            }
+            self.debounce.clear();
+            info!("Display change settled, re-reading the display layout");
+            capturer.reconfigure().await?;
+            self.refresh(capturer)?
+        } else if self.last_check.elapsed() >= HOTPLUG_CHECK_INTERVAL {
+            self.refresh(capturer)?
+        } else {
+            Vec::new()
+        };
+
+        let frame = match self.target {
+            CaptureTarget::Display { .. } => capturer.capture_frame().await?,
+            CaptureTarget::AllDisplays => {
+                let mut frames = Vec::with_capacity(self.displays.len());
+                for display in &self.displays {
+                    capturer.select_display(display.id)?;
+                    frames.push((display.clone(), capturer.capture_frame().await?));
+                }
+                composite(&frames)?
+            }
+        };
+
+        let size = (frame.width, frame.height);
+        match self.frame_size {
+            Some(last) if last != size && !just_settled => {
+                // Changed under us; wait for the change to settle, then
+                // announce the new size before sending frames at it
+                info!("Captured frame changed size from {}x{} to {}x{}", last.0, last.1, size.0, size.1);
+                self.debounce.signal(now);
+                return Ok((None, events));
+            }
+            Some(last) if last != size => {
+                let announced = events.iter().any(|event| {
+                    matches!(event, DisplayEvent::Switched { width, height, .. } if (*width, *height) == size)
+                });
+                if !announced {
+                    events.push(DisplayEvent::Switched {
+                        target: self.target,
+                        width: size.0,
+                        height: size.1,
+                        fallback: false,
+                    });
+                }
+            }
+            _ => {}
+        }
+        self.frame_size = Some(size);
+
+        Ok((Some(frame), events))
+    }
+
+    /// What the capturer reports changed in the frame [`Self::capture`]
+    /// returned; composited frames are diffed in full instead
+    pub fn take_damage<C: ScreenCapturer + ?Sized>(&self, capturer: &mut C) -> Option<Vec<DirtyRect>> {
+        let damage = capturer.take_damage();
+        match self.target {
+            CaptureTarget::Display { .. } => damage,
+            CaptureTarget::AllDisplays => None,
+        }
+    }
+
+    /// The DMA-BUF behind the frame [`Self::capture`] returned; composited
+    /// frames have none
+    #[cfg(target_os = "linux")]
+    pub fn take_dmabuf<C: ScreenCapturer + ?Sized>(&self, capturer: &mut C) -> Option<DmaBufFrame> {
+        let buffer = capturer.take_dmabuf();
+        match self.target {
+            CaptureTarget::Display { .. } => buffer,
+            CaptureTarget::AllDisplays => None,
+        }
+    }
+
+    /// Re-initialize `encoder` if `frame` has a different size than the last
+    /// frame it was set up for; returns whether it did
+    pub async fn prepare_encoder<E: VideoEncoder + ?Sized>(&mut self, encoder: &mut E, frame: &Frame, fps: u32) -> Result<bool> {
+        let size = (frame.width, frame.height);
+        if self.encoded_size == Some(size) {
+            return Ok(false);
+        }
+
+        info!("Re-initializing encoder for {}x{}", frame.width, frame.height);
+        encoder.initialize(frame.width, frame.height, fps).await?;
+        encoder.force_keyframe();
+        self.encoded_size = Some(size);
+        Ok(true)
+    }
+}
+
+/// Primary display, or the first one if none is marked primary
+fn primary_display(displays: &[DisplayInfo]) -> Option<&DisplayInfo> {
+    displays.iter().find(|display| display.is_primary).or_else(|| displays.first())
+}
+
+/// Origin and size of the rectangle spanning all displays
+fn desktop_bounds(displays: &[DisplayInfo]) -> Option<(i32, i32, u32, u32)> {
+    let left = displays.iter().map(|d| d.x).min()?;
+    let top = displays.iter().map(|d| d.y).min()?;
+    let right = displays.iter().map(|d| d.x + d.width as i32).max()?;
+    let bottom = displays.iter().map(|d| d.y + d.height as i32).max()?;
+    Some((left, top, (right - left) as u32, (bottom - top) as u32))
+}
+
+/// Paste each display's frame at its desktop position; gaps stay black
+fn composite(frames: &[(DisplayInfo, Frame)]) -> Result<Frame> {
+    let displays: Vec<DisplayInfo> = frames.iter().map(|(display, _)| display.clone()).collect();
+    let (left, top, width, height) = desktop_bounds(&displays)
+        .ok_or(GhostLinkError::Capture(CaptureError::NotInitialized))?;
+    let pixel_format = frames[0].1.pixel_format;
+    if !matches!(pixel_format, PixelFormat::RGBA | PixelFormat::BGRA)
+        || frames.iter().any(|(_, frame)| frame.pixel_format != pixel_format)
+    {
+        return Err(CaptureError::CaptureFailed {
+            reason: "Virtual desktop needs every display in the same 32-bit format".to_string(),
+        }.into());
+    }
+
+    let stride = width as usize * 4;
+    let mut data = vec![0u8; stride * height as usize];
+    for (display, frame) in frames {
+        let dest_x = (display.x - left) as usize;
+        let dest_y = (display.y - top) as usize;
+        let copy_width = (frame.width as usize).min(width as usize - dest_x);
+        let copy_height = (frame.height as usize).min(height as usize - dest_y);
+        let src_stride = (frame.stride as usize).max(frame.width as usize * 4);
+
+        for row in 0..copy_height {
+            let src = row * src_stride;
+            let Some(src_row) = frame.data.get(src..src + copy_width * 4) else {
+                break;
+            };
+            let dest = (dest_y + row) * stride + dest_x * 4;
+            data[dest..dest + copy_width * 4].copy_from_slice(src_row);
+        }
+    }
+
+    Ok(Frame {
+        data,
+        width,
+        height,
+        pixel_format,
+        stride: stride as u32,
+        timestamp: frames.iter().map(|(_, frame)| frame.timestamp).max().unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::EncoderInfo;
+
+    fn display(id: u32, x: i32, width: u32, height: u32, is_primary: bool) -> DisplayInfo {
+        DisplayInfo {
+            id,
+            name: format!("Display {}", id),
+            width,
+            height,
+            x,
+            y: 0,
+            is_primary,
+        }
+    }
+
+    /// Capturer with a primary 1920x1080 display and a 1280x1024 one to its right
+    struct FakeCapturer {
+        displays: Vec<DisplayInfo>,
+        selected: u32,
+        /// Next `take_display_change` reports a change
+        changed: bool,
+        reconfigured: u32,
+    }
+
+    impl FakeCapturer {
+        fn new() -> Self {
+            Self {
+                displays: vec![display(1, 0, 1920, 1080, true), display(2, 1920, 1280, 1024, false)],
+                selected: 1,
+                changed: false,
+                reconfigured: 0,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ScreenCapturer for FakeCapturer {
+        async fn initialize(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn capture_frame(&mut self) -> Result<Frame> {
+            let (width, height) = self.get_resolution();
+            Ok(Frame {
+                data: vec![self.selected as u8; (width * height * 4) as usize],
+                width,
+                height,
+                pixel_format: PixelFormat::BGRA,
+                stride: width * 4,
+                timestamp: 0,
+            })
+        }
+
+        fn get_display_info(&self) -> Vec<DisplayInfo> {
+            self.displays.clone()
+        }
+
+        fn select_display(&mut self, display_id: u32) -> Result<()> {
+            self.selected = display_id;
+            Ok(())
+        }
+
+        fn set_capture_region(&mut self, _x: i32, _y: i32, _width: u32, _height: u32) -> Result<()> {
+            Ok(())
+        }
+
+        fn get_resolution(&self) -> (u32, u32) {
+            self.displays.iter()
+                .find(|display| display.id == self.selected)
+                .map(|display| (display.width, display.height))
+                .unwrap_or((0, 0))
+        }
+
+        fn is_healthy(&self) -> bool {
+            true
+        }
+
+        fn take_display_change(&mut self) -> bool {
+            std::mem::take(&mut self.changed)
+        }
+
+        async fn reconfigure(&mut self) -> Result<()> {
+            self.reconfigured += 1;
+            Ok(())
+        }
+
+        async fn cleanup(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Encoder that records the sizes it was initialized with
+    #[derive(Default)]
+    struct FakeEncoder {
+        initialized: Vec<(u32, u32)>,
+        keyframes: u32,
+    }
+
+    #[async_trait::async_trait]
+    impl VideoEncoder for FakeEncoder {
+        async fn initialize(&mut self, width: u32, height: u32, _fps: u32) -> Result<()> {
+            self.initialized.push((width, height));
+            Ok(())
+        }
+
+        async fn encode_frame(&mut self, _frame: &Frame) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn get_encoder_info(&self) -> EncoderInfo {
+            EncoderInfo {
+                name: "Fake".to_string(),
+                hardware_accelerated: false,
+                supported_formats: vec![PixelFormat::BGRA],
+                max_resolution: (8192, 8192),
+                profile: None,
+                skipped: Vec::new(),
+            }
+        }
+
+        fn is_healthy(&self) -> bool {
+            true
+        }
+
+        fn force_keyframe(&mut self) {
+            self.keyframes += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_switch_display_reinitializes_encoder() {
+        let mut capturer = FakeCapturer::new();
+        let mut encoder = FakeEncoder::default();
+        let mut switcher = DisplaySwitcher::new(&capturer);
+        switcher.set_encoded_size(1920, 1080);
+
+        let frame = switcher.capture(&mut capturer).await.unwrap().0.unwrap();
+        assert!(!switcher.prepare_encoder(&mut encoder, &frame, 30).await.unwrap());
+
+        let event = switcher.select(&mut capturer, CaptureTarget::Display { display_id: 2 }).unwrap();
+        assert_eq!(event, DisplayEvent::Switched {
+            target: CaptureTarget::Display { display_id: 2 },
+            width: 1280,
+            height: 1024,
+            fallback: false,
+        });
+
+        let frame = switcher.capture(&mut capturer).await.unwrap().0.unwrap();
+        assert_eq!(frame.data[0], 2);
+        assert!(switcher.prepare_encoder(&mut encoder, &frame, 30).await.unwrap());
+        assert!(!switcher.prepare_encoder(&mut encoder, &frame, 30).await.unwrap());
+        assert_eq!(encoder.initialized, vec![(1280, 1024)]);
+        assert_eq!(encoder.keyframes, 1);
+
+        assert!(switcher.select(&mut capturer, CaptureTarget::Display { display_id: 7 }).is_err());
+        assert_eq!(switcher.target(), CaptureTarget::Display { display_id: 2 });
+    }
+
+    #[tokio::test]
+    async fn test_all_displays_composite() {
+        let mut capturer = FakeCapturer::new();
+        let mut switcher = DisplaySwitcher::new(&capturer);
+
+        let event = switcher.select(&mut capturer, CaptureTarget::AllDisplays).unwrap();
+        assert!(matches!(event, DisplayEvent::Switched { width: 3200, height: 1080, .. }));
+
+        let frame = switcher.capture(&mut capturer).await.unwrap().0.unwrap();
+        assert_eq!((frame.width, frame.height), (3200, 1080));
+        let pixel = |x: usize, y: usize| frame.data[(y * 3200 + x) * 4];
+        assert_eq!(pixel(0, 0), 1);
+        assert_eq!(pixel(1920, 0), 2);
+        // Below the shorter display
+        assert_eq!(pixel(1920, 1050), 0);
+    }
+
+    #[test]
+    fn test_unplugged_display_falls_back_to_primary() {
+        let mut capturer = FakeCapturer::new();
+        let mut switcher = DisplaySwitcher::new(&capturer);
+        switcher.select(&mut capturer, CaptureTarget::Display { display_id: 2 }).unwrap();
+        assert!(switcher.refresh(&mut capturer).unwrap().is_empty());
+
+        capturer.displays.pop();
+        let events = switcher.refresh(&mut capturer).unwrap();
+        assert_eq!(events, vec![
+            DisplayEvent::ListChanged {
+                displays: vec![display(1, 0, 1920, 1080, true)],
+                current: CaptureTarget::Display { display_id: 1 },
+            },
+            DisplayEvent::Switched {
+                target: CaptureTarget::Display { display_id: 1 },
+                width: 1920,
+                height: 1080,
+                fallback: true,
+            },
+        ]);
+        assert_eq!(capturer.selected, 1);
+    }
+
+    #[test]
+    fn test_change_debounce_waits_for_quiet() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut debounce = ChangeDebounce::new(Duration::from_millis(500), Duration::from_secs(3));
+        assert!(!debounce.pending());
+        assert!(!debounce.settled(at(0)));
+
+        debounce.signal(at(0));
+        debounce.signal(at(300));
+        assert!(debounce.pending());
+        assert!(!debounce.settled(at(700)));
+        assert!(debounce.settled(at(800)));
+
+        // A steady stream of changes holds frames back only so long
+        debounce.clear();
+        for step in 0..30 {
+            debounce.signal(at(1000 + step * 100));
+            assert!(!debounce.settled(at(1000 + step * 100)));
+        }
+        debounce.signal(at(4000));
+        assert!(debounce.settled(at(4000)));
+    }
+
+    /// Switcher that settles display changes after 50ms
+    fn quick_switcher(capturer: &FakeCapturer) -> DisplaySwitcher {
+        let mut switcher = DisplaySwitcher::new(capturer);
+        switcher.debounce = ChangeDebounce::new(Duration::from_millis(50), CHANGE_MAX_HOLD);
+        switcher
+    }
+
+    #[tokio::test]
+    async fn test_resolution_change_reinitializes_after_settling() {
+        let mut capturer = FakeCapturer::new();
+        let mut encoder = FakeEncoder::default();
+        let mut switcher = quick_switcher(&capturer);
+        let frame = switcher.capture(&mut capturer).await.unwrap().0.unwrap();
+        assert!(switcher.prepare_encoder(&mut encoder, &frame, 30).await.unwrap());
+
+        // Docking grows the primary display and announces it twice
+        capturer.displays[0].width = 2560;
+        capturer.displays[0].height = 1440;
+        for _ in 0..2 {
+            capturer.changed = true;
+            assert!(matches!(switcher.capture(&mut capturer).await.unwrap(), (None, events) if events.is_empty()));
+        }
+        assert_eq!(capturer.reconfigured, 0);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let (frame, events) = switcher.capture(&mut capturer).await.unwrap();
+        assert_eq!(capturer.reconfigured, 1);
+        assert_eq!(events, vec![
+            DisplayEvent::ListChanged {
+                displays: capturer.displays.clone(),
+                current: CaptureTarget::Display { display_id: 1 },
+            },
+            DisplayEvent::Switched {
+                target: CaptureTarget::Display { display_id: 1 },
+                width: 2560,
+                height: 1440,
+                fallback: false,
+            },
+        ]);
+
+        // The encoder follows only once the viewer has been told
+        let frame = frame.unwrap();
+        assert_eq!((frame.width, frame.height), (2560, 1440));
+        assert!(switcher.prepare_encoder(&mut encoder, &frame, 30).await.unwrap());
+        assert_eq!(encoder.initialized, vec![(1920, 1080), (2560, 1440)]);
+        assert_eq!(encoder.keyframes, 2);
+    }
+
+    #[tokio::test]
+    async fn test_frame_at_unannounced_size_is_held_back() {
+        let mut capturer = FakeCapturer::new();
+        let mut switcher = quick_switcher(&capturer);
+        switcher.capture(&mut capturer).await.unwrap().0.unwrap();
+
+        // Nothing reported the change; the frame size gives it away
+        capturer.displays[0].width = 1280;
+        capturer.displays[0].height = 720;
+        assert!(matches!(switcher.capture(&mut capturer).await.unwrap(), (None, events) if events.is_empty()));
+        assert!(matches!(switcher.capture(&mut capturer).await.unwrap(), (None, events) if events.is_empty()));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let (frame, events) = switcher.capture(&mut capturer).await.unwrap();
+        assert_eq!(frame.map(|frame| (frame.width, frame.height)), Some((1280, 720)));
+        assert!(events.contains(&DisplayEvent::Switched {
+            target: CaptureTarget::Display { display_id: 1 },
+            width: 1280,
+            height: 720,
+            fallback: false,
+        }));
+    }
+}
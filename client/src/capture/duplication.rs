@@ -0,0 +1,637 @@
+//! Recovery and geometry for desktop duplication
+//!
+//! A DXGI duplication belongs to the desktop it was created on. When input
+//! moves to another one (a UAC prompt, the lock screen, fast user
+//! switching) `AcquireNextFrame` fails with `DXGI_ERROR_ACCESS_LOST`; a
+//! driver update or GPU reset fails it with `DXGI_ERROR_DEVICE_REMOVED`.
+//! Either way the duplication is dead and [`Duplication`] creates it again
+//! on whichever desktop has input now, enumerating the outputs afresh since
+//! they may have changed too. The secure desktop UAC prompts run on can
+//! only be duplicated by SYSTEM; an agent running as the user is refused
+//! there, and sends a dimmed copy of the last frame saying so rather than
+//! leaving the technician with a frozen picture.
+//!
+//! Output coordinates are physical pixels of the virtual desktop, which is
+//! what DXGI reports to a per-monitor DPI aware process; mixing them with
+//! the scaled coordinates a DPI unaware process sees is what put regions
+//! and offsets in the wrong place on mixed-DPI setups.
+//!
+//! The DXGI calls sit behind [`Duplicator`] so the state machine can be
+//! tested without a GPU.
+
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use super::{DisplayInfo, Frame};
+use crate::error::{CaptureError, GhostLinkError, Result};
+use crate::recording::overlay;
+
+/// Shown over the last frame while the secure desktop has input
+pub const SECURE_DESKTOP_NOTICE: &str = "Secure desktop active - elevate the agent to view UAC prompts";
+
+/// Failed attempts at creating the duplication again before giving up,
+/// leaving the rest to the session watchdog
+const MAX_REOPEN_ATTEMPTS: u32 = 5;
+
+/// Pause between attempts at creating the duplication again
+const REOPEN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// DPI of a monitor at 100% scaling
+const BASE_DPI: u32 = 96;
+
+/// Why a duplication call failed, sorted by what to do about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DuplicationError {
+    /// Nothing changed on screen within the timeout
+    Timeout,
+    /// Input moved to another desktop; the duplication has to be created
+    /// again
+    AccessLost,
+    /// The GPU was removed or reset; the device has to be created again
+    DeviceLost,
+    /// The desktop with input cannot be duplicated by this process
+    SecureDesktop,
+    Failed(String),
+}
+
+impl std::fmt::Display for DuplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DuplicationError::Timeout => f.write_str("no new frame"),
+            DuplicationError::AccessLost => f.write_str("desktop switched"),
+            DuplicationError::DeviceLost => f.write_str("graphics device removed"),
+            DuplicationError::SecureDesktop => f.write_str("secure desktop cannot be duplicated"),
+            DuplicationError::Failed(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// A monitor as DXGI describes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputGeometry {
+    /// GDI device name, e.g. `\\.\DISPLAY1`; stable across re-enumeration
+    pub name: String,
+    /// Position and size in physical pixels of the virtual desktop
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+    /// Effective DPI, 96 at 100% scaling
+    pub dpi: u32,
+}
+
+impl OutputGeometry {
+    /// The primary monitor is the one at the virtual desktop's origin
+    pub fn is_primary(&self) -> bool {
+        self.left == 0 && self.top == 0
+    }
+
+    /// Scaling in percent, as Display Settings shows it
+    pub fn scale_percent(&self) -> u32 {
+        self.dpi * 100 / BASE_DPI
+    }
+
+    pub fn display_info(&self, id: u32) -> DisplayInfo {
+        DisplayInfo {
+            id,
+            name: format!("{} ({}%)", self.name, self.scale_percent()),
+            width: self.width,
+            height: self.height,
+            x: self.left,
+            y: self.top,
+            is_primary: self.is_primary(),
+        }
+    }
+
+    /// A region relative to the monitor's top-left corner, cut down to
+    /// the part that lies on it; `None` when nothing does
+    pub fn clip(&self, x: i32, y: i32, width: u32, height: u32) -> Option<Region> {
+        let left = x.max(0) as u32;
+        let top = y.max(0) as u32;
+        let right = (x as i64 + width as i64).clamp(0, self.width as i64) as u32;
+        let bottom = (y as i64 + height as i64).clamp(0, self.height as i64) as u32;
+        (right > left && bottom > top).then(|| Region { x: left, y: top, width: right - left, height: bottom - top })
+    }
+}
+
+/// Part of a monitor to capture, in its physical pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Copy this region out of a 4-byte-per-pixel frame
+    pub fn crop(&self, frame: &Frame) -> Frame {
+        let row_bytes = (self.width * 4) as usize;
+        let mut data = Vec::with_capacity(row_bytes * self.height as usize);
+        for row in self.y..self.y + self.height {
+            let start = (row * frame.stride + self.x * 4) as usize;
+            data.extend_from_slice(&frame.data[start..start + row_bytes]);
+        }
+        Frame {
+            data,
+            width: self.width,
+            height: self.height,
+            pixel_format: frame.pixel_format,
+            stride: self.width * 4,
+            timestamp: frame.timestamp,
+        }
+    }
+}
+
+/// The duplication calls; DXGI on Windows, mocks in tests
+pub trait Duplicator: Send {
+    /// The monitors attached to the desktop, enumerated afresh
+    fn outputs(&mut self) -> std::result::Result<Vec<OutputGeometry>, DuplicationError>;
+
+    /// Move to the desktop that has input and start duplicating `output`;
+    /// `new_device` after the GPU was lost
+    fn open(&mut self, output: &OutputGeometry, new_device: bool) -> std::result::Result<(), DuplicationError>;
+
+    /// The next frame, waiting up to `timeout` for one
+    fn acquire(&mut self, timeout: Duration) -> std::result::Result<Frame, DuplicationError>;
+
+    /// Release the duplication
+    fn close(&mut self);
+}
+
+/// Where the duplication stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DuplicationState {
+    /// Not opened yet, or cleaned up
+    Closed,
+    Active,
+    /// Lost and not created again yet, after `attempts` tries
+    Lost { attempts: u32 },
+    /// The secure desktop has input; retried until it goes away
+    SecureDesktop,
+    /// Could not be created again; the watchdog takes over
+    Failed(String),
+}
+
+/// A duplication that recovers from desktop switches and device loss
+pub struct Duplication<D> {
+    backend: D,
+    state: DuplicationState,
+    outputs: Vec<OutputGeometry>,
+    /// Name of the monitor captured, kept across re-enumeration
+    selected: Option<String>,
+    region: Option<(i32, i32, u32, u32)>,
+    device_lost: bool,
+    last_frame: Option<Frame>,
+    retry_at: Option<Instant>,
+    retry_interval: Duration,
+}
+
+impl<D: Duplicator> Duplication<D> {
+    pub fn new(backend: D) -> Self {
+        Self {
+            backend,
+            state: DuplicationState::Closed,
+            outputs: Vec::new(),
+            selected: None,
+            region: None,
+            device_lost: false,
+            last_frame: None,
+            retry_at: None,
+            retry_interval: REOPEN_INTERVAL,
+        }
+    }
+
+    pub fn with_retry_interval(mut self, interval: Duration) -> Self {
+        self.retry_interval = interval;
+        self
+    }
+
+    pub fn state(&self) -> &DuplicationState {
+        &self.state
+    }
+
+    pub fn outputs(&self) -> &[OutputGeometry] {
+        &self.outputs
+    }
+
+    /// The monitor being captured
+    pub fn output(&self) -> Option<&OutputGeometry> {
+        let selected = self.selected.as_deref();
+        self.outputs
+            .iter()
+            .find(|output| Some(output.name.as_str()) == selected)
+            .or_else(|| self.outputs.iter().find(|output| output.is_primary()))
+            .or_else(|| self.outputs.first())
+    }
+
+    /// Start duplicating; a secure desktop with input is not an error,
+    /// capture starts once it goes away
+    pub fn open(&mut self) -> Result<()> {
+        match self.reopen() {
+            Ok(()) | Err(DuplicationError::SecureDesktop) => Ok(()),
+            Err(e) => {
+                self.state = DuplicationState::Closed;
+                Err(GhostLinkError::Capture(CaptureError::InitializationFailed { reason: e.to_string() }))
+            }
+        }
+    }
+
+    /// Capture `output_index` of the last enumeration from now on
+    pub fn select(&mut self, output_index: usize) -> Result<()> {
+        let output = self.outputs.get(output_index).ok_or(GhostLinkError::Capture(CaptureError::InvalidDisplay {
+            id: output_index as u32,
+        }))?;
+        if self.selected.as_deref() == Some(output.name.as_str()) {
+            return Ok(());
+        }
+        self.selected = Some(output.name.clone());
+        self.region = None;
+        if self.state == DuplicationState::Active {
+            self.backend.close();
+            self.state = DuplicationState::Lost { attempts: 0 };
+            self.retry_at = None;
+        }
+        Ok(())
+    }
+
+    /// Capture only part of the monitor, relative to its top-left corner
+    pub fn set_region(&mut self, x: i32, y: i32, width: u32, height: u32) -> Result<()> {
+        let output = self.output().ok_or(GhostLinkError::Capture(CaptureError::NotInitialized))?;
+        if output.clip(x, y, width, height).is_none() {
+            return Err(GhostLinkError::Capture(CaptureError::InvalidResolution { width, height }));
+        }
+        self.region = Some((x, y, width, height));
+        Ok(())
+    }
+
+    /// Size of the frames captured, after the region
+    pub fn resolution(&self) -> (u32, u32) {
+        let Some(output) = self.output() else {
+            return (0, 0);
+        };
+        match self.region.and_then(|(x, y, width, height)| output.clip(x, y, width, height)) {
+            Some(region) => (region.width, region.height),
+            None => (output.width, output.height),
+        }
+    }
+
+    /// Whether the duplication works or is on its way back; what the
+    /// watchdog checks
+    pub fn is_healthy(&self) -> bool {
+        !matches!(self.state, DuplicationState::Closed | DuplicationState::Failed(_))
+    }
+
+    /// The next frame. A lost duplication is created again first; until
+    /// it is, the last frame is repeated, dimmed with a notice while the
+    /// secure desktop has input.
+    pub fn capture(&mut self, timeout: Duration) -> Result<Frame> {
+        match self.state.clone() {
+            DuplicationState::Closed => return Err(GhostLinkError::Capture(CaptureError::NotInitialized)),
+            DuplicationState::Failed(reason) => {
+                return Err(GhostLinkError::Capture(CaptureError::CaptureFailed { reason }));
+            }
+            DuplicationState::Active => {}
+            DuplicationState::Lost { .. } | DuplicationState::SecureDesktop => {
+                if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                    return self.stand_in();
+                }
+                match self.reopen() {
+                    Ok(()) => {}
+                    Err(DuplicationError::SecureDesktop) => return self.stand_in(),
+                    Err(e) => return self.reopen_failed(e),
+                }
+            }
+        }
+
+        match self.backend.acquire(timeout) {
+            Ok(frame) => {
+                let frame = self.cropped(frame);
+                self.last_frame = Some(frame.clone());
+                Ok(frame)
+            }
+            // Nothing moved; the screen still shows the last frame
+            Err(DuplicationError::Timeout) => self.stand_in(),
+            Err(e @ (DuplicationError::AccessLost | DuplicationError::DeviceLost)) => {
+                warn!("Desktop duplication lost ({}); creating it again", e);
+                self.backend.close();
+                self.device_lost |= e == DuplicationError::DeviceLost;
+                self.state = DuplicationState::Lost { attempts: 0 };
+                self.retry_at = None;
+                self.capture(Duration::ZERO)
+            }
+            Err(DuplicationError::SecureDesktop) => {
+                self.backend.close();
+                self.enter_secure_desktop();
+                self.stand_in()
+            }
+            Err(DuplicationError::Failed(reason)) => {
+                Err(GhostLinkError::Capture(CaptureError::FrameCaptureFailed { reason }))
+            }
+        }
+    }
+
+    pub fn close(&mut self) {
+        if self.state != DuplicationState::Closed {
+            self.backend.close();
+        }
+        self.state = DuplicationState::Closed;
+        self.last_frame = None;
+    }
+
+    /// Enumerate the outputs and duplicate the selected one on the desktop
+    /// that has input now
+    fn reopen(&mut self) -> std::result::Result<(), DuplicationError> {
+        self.outputs = self.backend.outputs()?;
+        let output = self.output().cloned().ok_or_else(|| DuplicationError::Failed("no monitor attached".to_string()))?;
+        match self.backend.open(&output, self.device_lost) {
+            Ok(()) => {
+                if self.state == DuplicationState::SecureDesktop {
+                    info!("Secure desktop closed; capturing {} again", output.name);
+                } else if self.state != DuplicationState::Closed {
+                    info!("Desktop duplication of {} recovered", output.name);
+                }
+                self.state = DuplicationState::Active;
+                self.device_lost = false;
+                self.retry_at = None;
+                Ok(())
+            }
+            Err(DuplicationError::SecureDesktop) => {
+                self.enter_secure_desktop();
+                Err(DuplicationError::SecureDesktop)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn enter_secure_desktop(&mut self) {
+        if self.state != DuplicationState::SecureDesktop {
+            warn!("Secure desktop has input and cannot be captured without elevation");
+        }
+        self.state = DuplicationState::SecureDesktop;
+        self.retry_at = Some(Instant::now() + self.retry_interval);
+    }
+
+    /// Count a failed attempt at creating the duplication again, giving up
+    /// after too many
+    fn reopen_failed(&mut self, error: DuplicationError) -> Result<Frame> {
+        let attempts = match self.state {
+            DuplicationState::Lost { attempts } => attempts + 1,
+            _ => 1,
+        };
+        if attempts >= MAX_REOPEN_ATTEMPTS {
+            let reason = format!("desktop duplication could not be recreated: {}", error);
+            warn!("{}", reason);
+            self.state = DuplicationState::Failed(reason.clone());
+            return Err(GhostLinkError::Capture(CaptureError::CaptureFailed { reason }));
+        }
+        warn!("Recreating desktop duplication failed (attempt {} of {}): {}", attempts, MAX_REOPEN_ATTEMPTS, error);
+        self.state = DuplicationState::Lost { attempts };
+        self.retry_at = Some(Instant::now() + self.retry_interval);
+        self.stand_in()
+    }
+
+    /// What to send while no new frame can be had
+    fn stand_in(&self) -> Result<Frame> {
+        let Some(last) = &self.last_frame else {
+            return Err(GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
+                reason: "no frame captured yet".to_string(),
+            }));
+        };
+        if self.state != DuplicationState::SecureDesktop {
+            return Ok(last.clone());
+        }
+        Ok(secure_desktop_placeholder(last))
+    }
+
+    fn cropped(&self, frame: Frame) -> Frame {
+        let region = self.output().zip(self.region).and_then(|(output, (x, y, width, height))| output.clip(x, y, width, height));
+        match region {
+            Some(region) if (region.width, region.height) != (frame.width, frame.height) => region.crop(&frame),
+            _ => frame,
+        }
+    }
+}
+
+/// `frame` dimmed, with [`SECURE_DESKTOP_NOTICE`] along the bottom
+pub fn secure_desktop_placeholder(frame: &Frame) -> Frame {
+    let mut placeholder = frame.clone();
+    for pixel in placeholder.data.chunks_exact_mut(4) {
+        for channel in &mut pixel[..3] {
+            *channel /= 3;
+        }
+    }
+    // Black and white read the same in BGRA
+    overlay::draw_caption(&mut placeholder.data, placeholder.width, placeholder.height, SECURE_DESKTOP_NOTICE);
+    placeholder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::PixelFormat;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    fn monitor(name: &str, left: i32, width: u32, height: u32, dpi: u32) -> OutputGeometry {
+        OutputGeometry { name: name.to_string(), left, top: 0, width, height, dpi }
+    }
+
+    fn frame(width: u32, height: u32, value: u8) -> Frame {
+        Frame {
+            data: vec![value; (width * height * 4) as usize],
+            width,
+            height,
+            pixel_format: PixelFormat::BGRA,
+            stride: width * 4,
+            timestamp: 0,
+        }
+    }
+
+    /// Answers each call from a script; unscripted opens succeed and
+    /// unscripted acquires return a frame of the output's size
+    #[derive(Default)]
+    struct Script {
+        outputs: Vec<OutputGeometry>,
+        opens: VecDeque<std::result::Result<(), DuplicationError>>,
+        acquires: VecDeque<std::result::Result<Frame, DuplicationError>>,
+        calls: Vec<String>,
+    }
+
+    #[derive(Clone, Default)]
+    struct MockDuplicator(Arc<Mutex<Script>>);
+
+    impl MockDuplicator {
+        fn with_outputs(outputs: Vec<OutputGeometry>) -> Self {
+            let mock = Self::default();
+            mock.0.lock().unwrap().outputs = outputs;
+            mock
+        }
+
+        fn script(&self) -> std::sync::MutexGuard<'_, Script> {
+            self.0.lock().unwrap()
+        }
+
+        fn calls(&self) -> Vec<String> {
+            std::mem::take(&mut self.script().calls)
+        }
+    }
+
+    impl Duplicator for MockDuplicator {
+        fn outputs(&mut self) -> std::result::Result<Vec<OutputGeometry>, DuplicationError> {
+            let mut script = self.script();
+            script.calls.push("outputs".to_string());
+            Ok(script.outputs.clone())
+        }
+
+        fn open(&mut self, output: &OutputGeometry, new_device: bool) -> std::result::Result<(), DuplicationError> {
+            let mut script = self.script();
+            script.calls.push(format!("open {}{}", output.name, if new_device { " new device" } else { "" }));
+            script.opens.pop_front().unwrap_or(Ok(()))
+        }
+
+        fn acquire(&mut self, _timeout: Duration) -> std::result::Result<Frame, DuplicationError> {
+            let mut script = self.script();
+            script.calls.push("acquire".to_string());
+            let (width, height) = (script.outputs[0].width, script.outputs[0].height);
+            script.acquires.pop_front().unwrap_or_else(|| Ok(frame(width, height, 200)))
+        }
+
+        fn close(&mut self) {
+            self.script().calls.push("close".to_string());
+        }
+    }
+
+    #[test]
+    fn test_access_lost_recreates_the_duplication() {
+        let mock = MockDuplicator::with_outputs(vec![monitor("DISPLAY1", 0, 4, 2, 96)]);
+        let mut duplication = Duplication::new(mock.clone());
+        duplication.open().unwrap();
+        duplication.capture(Duration::ZERO).unwrap();
+        mock.calls();
+
+        // A UAC prompt came and went between two frames
+        mock.script().acquires.push_back(Err(DuplicationError::AccessLost));
+        let frame = duplication.capture(Duration::ZERO).unwrap();
+        assert_eq!(frame.data[0], 200);
+        assert_eq!(mock.calls(), ["acquire", "close", "outputs", "open DISPLAY1", "acquire"]);
+        assert_eq!(*duplication.state(), DuplicationState::Active);
+
+        // A GPU reset also gets a new device
+        mock.script().acquires.push_back(Err(DuplicationError::DeviceLost));
+        duplication.capture(Duration::ZERO).unwrap();
+        assert_eq!(mock.calls(), ["acquire", "close", "outputs", "open DISPLAY1 new device", "acquire"]);
+    }
+
+    #[test]
+    fn test_secure_desktop_sends_placeholder_until_it_closes() {
+        let mock = MockDuplicator::with_outputs(vec![monitor("DISPLAY1", 0, 200, 100, 96)]);
+        let mut duplication = Duplication::new(mock.clone()).with_retry_interval(Duration::ZERO);
+        duplication.open().unwrap();
+        duplication.capture(Duration::ZERO).unwrap();
+
+        {
+            let mut script = mock.script();
+            script.acquires.push_back(Err(DuplicationError::AccessLost));
+            script.opens.push_back(Err(DuplicationError::SecureDesktop));
+            script.opens.push_back(Err(DuplicationError::SecureDesktop));
+        }
+        let placeholder = duplication.capture(Duration::ZERO).unwrap();
+        assert_eq!(*duplication.state(), DuplicationState::SecureDesktop);
+        assert!(duplication.is_healthy());
+        // Dimmed, with the notice's black band at the bottom left
+        assert_eq!(placeholder.data[0], 200 / 3);
+        let bottom_left = ((placeholder.height - 1) * placeholder.stride) as usize;
+        assert_eq!(placeholder.data[bottom_left], 0);
+
+        // Still up: another placeholder
+        assert_eq!(duplication.capture(Duration::ZERO).unwrap().data[0], 200 / 3);
+        // The prompt was answered
+        let frame = duplication.capture(Duration::ZERO).unwrap();
+        assert_eq!(frame.data[0], 200);
+        assert_eq!(*duplication.state(), DuplicationState::Active);
+    }
+
+    #[test]
+    fn test_gives_up_after_repeated_failures() {
+        let mock = MockDuplicator::with_outputs(vec![monitor("DISPLAY1", 0, 4, 2, 96)]);
+        let mut duplication = Duplication::new(mock.clone()).with_retry_interval(Duration::ZERO);
+        duplication.open().unwrap();
+        duplication.capture(Duration::ZERO).unwrap();
+
+        {
+            let mut script = mock.script();
+            script.acquires.push_back(Err(DuplicationError::DeviceLost));
+            for _ in 0..MAX_REOPEN_ATTEMPTS {
+                script.opens.push_back(Err(DuplicationError::Failed("no adapter".to_string())));
+            }
+        }
+        // The last frame stands in while it retries
+        for _ in 1..MAX_REOPEN_ATTEMPTS {
+            assert!(duplication.capture(Duration::ZERO).is_ok());
+            assert!(duplication.is_healthy());
+        }
+        let err = duplication.capture(Duration::ZERO).unwrap_err();
+        assert!(err.to_string().contains("no adapter"), "{}", err);
+        assert!(!duplication.is_healthy());
+        assert!(matches!(duplication.state(), DuplicationState::Failed(_)));
+    }
+
+    #[test]
+    fn test_reenumeration_keeps_the_selected_monitor() {
+        let mock = MockDuplicator::with_outputs(vec![
+            monitor("DISPLAY1", 0, 3840, 2160, 192),
+            monitor("DISPLAY2", 3840, 1920, 1080, 96),
+        ]);
+        let mut duplication = Duplication::new(mock.clone());
+        duplication.open().unwrap();
+        mock.calls();
+        duplication.select(1).unwrap();
+        duplication.capture(Duration::ZERO).unwrap();
+        assert_eq!(mock.calls(), ["close", "outputs", "open DISPLAY2", "acquire"]);
+
+        // After a switch the monitors come back in another order
+        mock.script().outputs.reverse();
+        mock.script().acquires.push_back(Err(DuplicationError::AccessLost));
+        duplication.capture(Duration::ZERO).unwrap();
+        assert!(mock.calls().contains(&"open DISPLAY2".to_string()));
+    }
+
+    #[test]
+    fn test_mixed_dpi_geometry_is_in_physical_pixels() {
+        let laptop = monitor("DISPLAY1", 0, 2880, 1800, 192);
+        let external = monitor("DISPLAY2", 2880, 1920, 1080, 96);
+
+        let info = external.display_info(1);
+        assert_eq!((info.x, info.y, info.width, info.height), (2880, 0, 1920, 1080));
+        assert!(!info.is_primary);
+        assert_eq!(info.name, "DISPLAY2 (100%)");
+        assert_eq!(laptop.display_info(0).name, "DISPLAY1 (200%)");
+        assert!(laptop.is_primary());
+
+        // Regions are clipped to the monitor they are on
+        assert_eq!(external.clip(1800, 900, 400, 400), Some(Region { x: 1800, y: 900, width: 120, height: 180 }));
+        assert_eq!(external.clip(-10, -10, 20, 20), Some(Region { x: 0, y: 0, width: 10, height: 10 }));
+        assert_eq!(external.clip(1920, 0, 10, 10), None);
+    }
+
+    #[test]
+    fn test_region_crops_captured_frames() {
+        let mock = MockDuplicator::with_outputs(vec![monitor("DISPLAY1", 0, 4, 4, 96)]);
+        let mut duplication = Duplication::new(mock.clone());
+        duplication.open().unwrap();
+        duplication.set_region(1, 2, 2, 5).unwrap();
+        assert_eq!(duplication.resolution(), (2, 2));
+
+        let mut source = frame(4, 4, 0);
+        for (i, pixel) in source.data.chunks_exact_mut(4).enumerate() {
+            pixel[0] = i as u8;
+        }
+        mock.script().acquires.push_back(Ok(source));
+        let cropped = duplication.capture(Duration::ZERO).unwrap();
+        assert_eq!((cropped.width, cropped.height, cropped.stride), (2, 2, 8));
+        let firsts: Vec<u8> = cropped.data.chunks_exact(4).map(|pixel| pixel[0]).collect();
+        assert_eq!(firsts, [9, 10, 13, 14]);
+        assert!(duplication.set_region(10, 10, 2, 2).is_err());
+    }
+}
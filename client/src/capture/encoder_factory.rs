@@ -1,12 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Mutex;
 use tracing::{info, warn};
 
 use crate::capture::{
-    VideoEncoderEnum, 
+    Frame, PixelFormat, VideoEncoder, VideoEncoderEnum,
     h264_encoder::H264Encoder,
     hevc_encoder::HevcEncoder,
     encoding::SoftwareEncoder,
+    encoder_profile::{EncoderProfile, EncodingPolicy},
+    negotiation::StreamCodec,
 };
-use crate::error::Result;
+#[cfg(feature = "nvenc")]
+use crate::capture::nvenc_encoder::{NvencCodec, NvencEncoder};
+use crate::error::{CaptureError, Result};
+use crate::session::SessionType;
+
+/// Environment variable naming an encoder to use instead of the fallback
+/// chain, for debugging
+pub const ENCODER_ENV: &str = "GHOSTLINK_ENCODER";
+
+/// Width and height of the dummy frame encoders are probed with; even, as
+/// 4:2:0 chroma needs
+const PROBE_SIZE: u32 = 64;
+
+const PROBE_FPS: u32 = 30;
+
+/// Outcome of the last walk down the fallback chain. Probing opens the GPU,
+/// so it happens once per process; later captures reuse the winner.
+static PROBED: Mutex<Option<ProbeCache>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+struct ProbeCache {
+    selected: EncoderKind,
+    skipped: Vec<SkippedEncoder>,
+}
+
+/// Encoders of the fallback chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncoderKind {
+    NvencAv1,
+    NvencH265,
+    NvencH264,
+    Qsv,
+    VideoToolbox,
+    /// x264 through FFmpeg, or PNG frames in builds without it
+    SoftwareH264,
+    /// JPEG frames; needs nothing from the machine
+    Software,
+}
+
+impl EncoderKind {
+    /// The fallback chain, best first
+    pub const CHAIN: [EncoderKind; 7] = [
+        EncoderKind::NvencAv1,
+        EncoderKind::NvencH265,
+        EncoderKind::NvencH264,
+        EncoderKind::Qsv,
+        EncoderKind::VideoToolbox,
+        EncoderKind::SoftwareH264,
+        EncoderKind::Software,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EncoderKind::NvencAv1 => "nvenc-av1",
+            EncoderKind::NvencH265 => "nvenc-h265",
+            EncoderKind::NvencH264 => "nvenc-h264",
+            EncoderKind::Qsv => "qsv",
+            EncoderKind::VideoToolbox => "videotoolbox",
+            EncoderKind::SoftwareH264 => "software-h264",
+            EncoderKind::Software => "software",
+        }
+    }
+
+    /// Codec of the frames this encoder produces
+    pub fn codec(&self) -> StreamCodec {
+        match self {
+            EncoderKind::NvencAv1 => StreamCodec::Av1,
+            EncoderKind::NvencH265 => StreamCodec::H265,
+            EncoderKind::NvencH264 | EncoderKind::Qsv | EncoderKind::VideoToolbox => StreamCodec::H264,
+            EncoderKind::SoftwareH264 if cfg!(feature = "x264-encoder") => StreamCodec::H264,
+            EncoderKind::SoftwareH264 => StreamCodec::Png,
+            EncoderKind::Software => StreamCodec::Jpeg,
+        }
+    }
+
+    /// Create an unprobed encoder of this kind, if this build has it
+    pub async fn create(self) -> Result<VideoEncoderEnum> {
+        match self {
+            #[cfg(feature = "nvenc")]
+            EncoderKind::NvencAv1 => Ok(VideoEncoderEnum::NvencAV1(NvencEncoder::new(NvencCodec::AV1))),
+            #[cfg(feature = "nvenc")]
+            EncoderKind::NvencH265 => Ok(VideoEncoderEnum::NvencH265(NvencEncoder::new(NvencCodec::H265))),
+            #[cfg(feature = "nvenc")]
+            EncoderKind::NvencH264 => Ok(VideoEncoderEnum::NvencH264(NvencEncoder::new(NvencCodec::H264))),
+            #[cfg(feature = "qsv")]
+            EncoderKind::Qsv => Ok(VideoEncoderEnum::Qsv(crate::capture::encoding::QsvEncoder::new().await?)),
+            #[cfg(feature = "videotoolbox")]
+            EncoderKind::VideoToolbox => {
+                Ok(VideoEncoderEnum::VideoToolbox(crate::capture::encoding::VideoToolboxEncoder::new().await?))
+            }
+            EncoderKind::SoftwareH264 => Ok(VideoEncoderEnum::H264(H264Encoder::new())),
+            EncoderKind::Software => Ok(VideoEncoderEnum::Software(SoftwareEncoder::new().await?)),
+            #[allow(unreachable_patterns)]
+            _ => Err(CaptureError::EncoderUnavailable {
+                encoder_type: format!("{} (not compiled in)", self),
+            }
+            .into()),
+        }
+    }
+}
+
+impl std::fmt::Display for EncoderKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for EncoderKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let name = s.to_lowercase().replace('_', "-");
+        EncoderKind::CHAIN
+            .into_iter()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown encoder: {}", s))
+    }
+}
+
+/// An encoder the fallback chain passed over, and why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedEncoder {
+    pub encoder: EncoderKind,
+    pub reason: String,
+}
+
+/// Encoder picked from the fallback chain
+pub struct EncoderSelection<E> {
+    pub kind: EncoderKind,
+    /// Initialized at the probe size; re-initialize before use
+    pub encoder: E,
+    /// Candidates ahead of `kind` and why they failed
+    pub skipped: Vec<SkippedEncoder>,
+}
+
+/// Walk `candidates` in order, creating each with `create` and test-encoding
+/// a dummy frame; the first that gets through wins
+pub async fn select_encoder<E, F, Fut>(candidates: &[EncoderKind], mut create: F) -> Result<EncoderSelection<E>>
+where
+    E: VideoEncoder,
+    F: FnMut(EncoderKind) -> Fut,
+    Fut: Future<Output = Result<E>>,
+{
+    let mut skipped = Vec::new();
+    for &kind in candidates {
+        let attempt = match create(kind).await {
+            Ok(mut encoder) => probe(&mut encoder).await.map(|_| encoder),
+            Err(e) => Err(e),
+        };
+        match attempt {
+            Ok(encoder) => {
+                info!("Using {} encoder ({} skipped)", kind, skipped.len());
+                return Ok(EncoderSelection { kind, encoder, skipped });
+            }
+            Err(e) => {
+                warn!("Skipping {} encoder: {}", kind, e);
+                skipped.push(SkippedEncoder { encoder: kind, reason: e.to_string() });
+            }
+        }
+    }
+
+    let reasons: Vec<String> = skipped.iter().map(|s| format!("{}: {}", s.encoder, s.reason)).collect();
+    Err(CaptureError::EncoderUnavailable { encoder_type: reasons.join("; ") }.into())
+}
+
+/// Initialize `encoder` and encode a dummy frame with it
+async fn probe<E: VideoEncoder>(encoder: &mut E) -> Result<()> {
+    encoder.initialize(PROBE_SIZE, PROBE_SIZE, PROBE_FPS).await?;
+    let frame = Frame {
+        data: vec![0; (PROBE_SIZE * PROBE_SIZE * 4) as usize],
+        width: PROBE_SIZE,
+        height: PROBE_SIZE,
+        pixel_format: PixelFormat::RGBA,
+        stride: PROBE_SIZE * 4,
+        timestamp: 0,
+    };
+    // A buffering encoder may hold the frame back; accepting it is enough
+    encoder.encode_frame(&frame).await?;
+    // The real stream opens on its own keyframe, not a delta of the probe
+    encoder.force_keyframe();
+    Ok(())
+}
+
+/// Encoders the last walk down the fallback chain passed over
+pub fn skipped_encoders() -> Vec<SkippedEncoder> {
+    PROBED.lock().unwrap().as_ref().map(|cache| cache.skipped.clone()).unwrap_or_default()
+}
 
 /// Encoder preferences for different use cases
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -227,6 +419,72 @@ impl EncoderFactory {
         }
     }
     
+    /// Create the first encoder of the fallback chain that works on this
+    /// machine, or `forced` regardless of the chain
+    pub async fn create_probed(forced: Option<EncoderKind>) -> Result<EncoderSelection<VideoEncoderEnum>> {
+        if let Some(kind) = forced {
+            info!("Encoder forced to {}", kind);
+            let selection = select_encoder(&[kind], EncoderKind::create).await?;
+            *PROBED.lock().unwrap() = Some(ProbeCache { selected: kind, skipped: Vec::new() });
+            return Ok(selection);
+        }
+
+        // Encoders that failed once are not tried again
+        let cached = PROBED.lock().unwrap().clone();
+        if let Some(cache) = cached {
+            match cache.selected.create().await {
+                Ok(encoder) => return Ok(EncoderSelection { kind: cache.selected, encoder, skipped: cache.skipped }),
+                Err(e) => warn!("Previously probed {} encoder is gone: {}", cache.selected, e),
+            }
+        }
+
+        let selection = select_encoder(&EncoderKind::CHAIN, EncoderKind::create).await?;
+        *PROBED.lock().unwrap() = Some(ProbeCache { selected: selection.kind, skipped: selection.skipped.clone() });
+        Ok(selection)
+    }
+
+    /// Create the first of `candidates` that works on this machine, e.g. the
+    /// encoders a viewer can decode. Encoders the fallback chain already
+    /// found broken are passed over.
+    pub async fn create_from(candidates: &[EncoderKind]) -> Result<EncoderSelection<VideoEncoderEnum>> {
+        let broken = skipped_encoders();
+        let usable: Vec<EncoderKind> = candidates
+            .iter()
+            .copied()
+            .filter(|kind| !broken.iter().any(|skipped| skipped.encoder == *kind))
+            .collect();
+        if usable.is_empty() {
+            let names: Vec<&str> = candidates.iter().map(|kind| kind.name()).collect();
+            return Err(CaptureError::EncoderUnavailable {
+                encoder_type: format!("none of [{}] works on this machine", names.join(", ")),
+            }
+            .into());
+        }
+        select_encoder(&usable, EncoderKind::create).await
+    }
+    
+    /// Create an encoder configured with the profile for a session type,
+    /// honouring any override in the organization's encoding policy
+    pub async fn create_for_session(
+        session_type: SessionType,
+        policy: &EncodingPolicy,
+        target_fps: u32,
+    ) -> Result<VideoEncoderEnum> {
+        let profile = policy.profile_for(session_type);
+        info!("Using {} encoder profile for {} session", profile, session_type);
+        Self::create_with_profile(profile, target_fps).await
+    }
+    
+    /// Create an encoder with a preset profile applied
+    pub async fn create_with_profile(
+        profile: EncoderProfile,
+        target_fps: u32,
+    ) -> Result<VideoEncoderEnum> {
+        let mut encoder = Self::create_best_encoder(profile.encoder_preference(), target_fps).await?;
+        encoder.set_profile(profile);
+        Ok(encoder)
+    }
+    
     /// Get recommended encoder for streaming scenarios
     pub async fn create_streaming_encoder(
         bitrate_kbps: u32,
@@ -253,7 +511,9 @@ impl EncoderFactory {
         };
         
         info!("Creating recording encoder for quality level {}", quality_level);
-        Self::create_best_encoder(preference, 30).await // 30fps default for recording
+        let mut encoder = Self::create_best_encoder(preference, 30).await?; // 30fps default for recording
+        encoder.set_profile(EncoderProfile::Archival);
+        Ok(encoder)
     }
     
     /// List all available encoders on this system
@@ -292,4 +552,113 @@ impl EncoderFactory {
         info!("Available encoders: {:?}", encoders);
         encoders
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::EncoderInfo;
+    use crate::error::GhostLinkError;
+
+    /// Stands in for an encoder whose driver may be missing
+    struct MockEncoder {
+        kind: EncoderKind,
+        works: bool,
+        initialized: Vec<(u32, u32)>,
+        frames: u32,
+        keyframe_forced: bool,
+    }
+
+    impl MockEncoder {
+        fn new(kind: EncoderKind, works: bool) -> Self {
+            Self { kind, works, initialized: Vec::new(), frames: 0, keyframe_forced: false }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl VideoEncoder for MockEncoder {
+        async fn initialize(&mut self, width: u32, height: u32, _fps: u32) -> Result<()> {
+            if !self.works {
+                return Err(GhostLinkError::Other(format!("no {} device", self.kind)));
+            }
+            self.initialized.push((width, height));
+            Ok(())
+        }
+
+        async fn encode_frame(&mut self, frame: &Frame) -> Result<Vec<u8>> {
+            assert_eq!(frame.data.len(), (frame.width * frame.height * 4) as usize);
+            self.frames += 1;
+            Ok(vec![0; 8])
+        }
+
+        fn get_encoder_info(&self) -> EncoderInfo {
+            EncoderInfo {
+                name: self.kind.to_string(),
+                hardware_accelerated: false,
+                supported_formats: vec![PixelFormat::RGBA],
+                max_resolution: (8192, 8192),
+                profile: None,
+                skipped: Vec::new(),
+            }
+        }
+
+        fn is_healthy(&self) -> bool {
+            self.works
+        }
+
+        fn force_keyframe(&mut self) {
+            self.keyframe_forced = true;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_falls_through_to_working_encoder() {
+        let mut created = Vec::new();
+        let selection = select_encoder(&EncoderKind::CHAIN, |kind| {
+            created.push(kind);
+            async move { Ok(MockEncoder::new(kind, kind == EncoderKind::NvencH264)) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(selection.kind, EncoderKind::NvencH264);
+        assert_eq!(created, [EncoderKind::NvencAv1, EncoderKind::NvencH265, EncoderKind::NvencH264]);
+        let skipped: Vec<_> = selection.skipped.iter().map(|s| s.encoder).collect();
+        assert_eq!(skipped, [EncoderKind::NvencAv1, EncoderKind::NvencH265]);
+        assert!(selection.skipped[0].reason.contains("no nvenc-av1 device"));
+
+        // The winner was really tried, and starts the stream on a keyframe
+        let encoder = selection.encoder;
+        assert_eq!(encoder.initialized, [(PROBE_SIZE, PROBE_SIZE)]);
+        assert_eq!(encoder.frames, 1);
+        assert!(encoder.keyframe_forced);
+    }
+
+    #[tokio::test]
+    async fn test_chain_reports_every_failure() {
+        let candidates = [EncoderKind::Qsv, EncoderKind::VideoToolbox];
+        let result = select_encoder(&candidates, |kind| async move {
+            if kind == EncoderKind::Qsv {
+                Err(CaptureError::EncoderUnavailable { encoder_type: "qsv (not compiled in)".to_string() }.into())
+            } else {
+                Ok(MockEncoder::new(kind, false))
+            }
+        })
+        .await;
+
+        let Err(GhostLinkError::Capture(CaptureError::EncoderUnavailable { encoder_type })) = result else {
+            panic!("no encoder works")
+        };
+        assert!(encoder_type.contains("qsv: Capture error: Encoder not available: qsv (not compiled in)"), "{}", encoder_type);
+        assert!(encoder_type.contains("videotoolbox: no videotoolbox device"), "{}", encoder_type);
+    }
+
+    #[test]
+    fn test_encoder_names() {
+        for kind in EncoderKind::CHAIN {
+            assert_eq!(kind.name().parse::<EncoderKind>().unwrap(), kind);
+        }
+        assert_eq!("NVENC_H265".parse::<EncoderKind>().unwrap(), EncoderKind::NvencH265);
+        assert!("nvenc".parse::<EncoderKind>().is_err());
+        assert_eq!(serde_json::to_string(&EncoderKind::SoftwareH264).unwrap(), "\"software-h264\"");
+    }
+}
@@ -0,0 +1,481 @@
+//! Encoder preset profiles
+//!
+//! A profile trades latency against image quality and is picked from the
+//! session type, unless the organization's policy overrides it:
+//!
+//! | Profile         | Default for    | GOP  | B-frames | Rate control       | x264 / x265 preset, tune   | NVENC preset, tune | Software     |
+//! |-----------------|----------------|------|----------|--------------------|----------------------------|--------------------|--------------|
+//! | `low-latency`   | Console        | 1 s  | 0        | CBR at target      | `ultrafast`, `zerolatency` | `p1`, `ull`        | JPEG q70     |
+//! | `balanced`      | AdHoc          | 2 s  | 0        | VBR, CRF 23 capped | `veryfast`, `zerolatency`  | `p4`, `ll`         | JPEG q80     |
+//! | `quality`       | Backstage      | 4 s  | 0        | CRF 18             | `medium`, `stillimage`     | `p6`, `hq`         | JPEG q95     |
+//! | `archival`      | recordings     | 10 s | 3        | CRF 20             | `slow`, none               | `p7`, `hq`         | PNG lossless |
+//! | `low-bandwidth` | on request     | 10 s | 0        | VBR, CRF 32 capped | `veryfast`, `stillimage`   | `p4`, `ll`         | JPEG q40     |
+//!
+//! Only `archival` enables B-frames; the others stay at zero because every
+//! B-frame adds a frame of delay to a live session.
+//!
+//! `low-bandwidth` goes further than encoder options: frames are reduced to
+//! 256 colors before encoding, the frame rate is held at 5 fps, and the
+//! frame differ works with smaller tiles so less of the screen counts as
+//! changed.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::capture::adaptive::AdaptiveConfig;
+use crate::capture::bandwidth::ColorDepth;
+use crate::capture::encoder_factory::{EncoderKind, EncoderPreference, ENCODER_ENV};
+use crate::capture::frame_diff::DEFAULT_TILE_SIZE;
+use crate::session::SessionType;
+
+/// Tile edge for `low-bandwidth`, so a blinking caret does not resend a
+/// large block around it
+const LOW_BANDWIDTH_TILE_SIZE: u32 = 32;
+
+/// Named encoder preset profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncoderProfile {
+    /// Interactive control: smallest delay, short GOP, constant bitrate
+    LowLatency,
+    /// General purpose streaming
+    Balanced,
+    /// Readable small text for maintenance work, at the cost of bandwidth
+    Quality,
+    /// Recordings: best compression, latency does not matter
+    Archival,
+    /// Cellular hotspots and other metered links: every byte counts
+    LowBandwidth,
+}
+
+impl EncoderProfile {
+    /// Default profile for a session type
+    pub fn for_session_type(session_type: SessionType) -> Self {
+        match session_type {
+            SessionType::Console => EncoderProfile::LowLatency,
+            SessionType::AdHoc => EncoderProfile::Balanced,
+            SessionType::Backstage => EncoderProfile::Quality,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EncoderProfile::LowLatency => "low-latency",
+            EncoderProfile::Balanced => "balanced",
+            EncoderProfile::Quality => "quality",
+            EncoderProfile::Archival => "archival",
+            EncoderProfile::LowBandwidth => "low-bandwidth",
+        }
+    }
+
+    /// Encoder selection preference matching this profile
+    pub fn encoder_preference(&self) -> EncoderPreference {
+        match self {
+            EncoderProfile::LowLatency => EncoderPreference::MaxPerformance,
+            EncoderProfile::Balanced | EncoderProfile::Quality => EncoderPreference::Balanced,
+            EncoderProfile::Archival | EncoderProfile::LowBandwidth => EncoderPreference::MinBandwidth,
+        }
+    }
+
+    /// Concrete settings of this profile for an encoder family
+    pub fn settings(&self, family: CodecFamily) -> EncoderSettings {
+        let (gop_seconds, b_frames, rate_control, jpeg_quality, lossless) = match self {
+            EncoderProfile::LowLatency => (1, 0, RateControl::Cbr, 70, false),
+            EncoderProfile::Balanced => (2, 0, RateControl::CappedCrf(23), 80, false),
+            EncoderProfile::Quality => (4, 0, RateControl::Crf(18), 95, false),
+            EncoderProfile::Archival => (10, 3, RateControl::Crf(20), 100, true),
+            EncoderProfile::LowBandwidth => (10, 0, RateControl::CappedCrf(32), 40, false),
+        };
+
+        let (preset, tune) = match (family, self) {
+            (CodecFamily::Nvenc, EncoderProfile::LowLatency) => ("p1", Some("ull")),
+            (CodecFamily::Nvenc, EncoderProfile::Balanced) => ("p4", Some("ll")),
+            (CodecFamily::Nvenc, EncoderProfile::Quality) => ("p6", Some("hq")),
+            (CodecFamily::Nvenc, EncoderProfile::Archival) => ("p7", Some("hq")),
+            (CodecFamily::Nvenc, EncoderProfile::LowBandwidth) => ("p4", Some("ll")),
+            (_, EncoderProfile::LowLatency) => ("ultrafast", Some("zerolatency")),
+            (_, EncoderProfile::Balanced) => ("veryfast", Some("zerolatency")),
+            (_, EncoderProfile::Quality) => ("medium", Some("stillimage")),
+            (_, EncoderProfile::Archival) => ("slow", None),
+            (_, EncoderProfile::LowBandwidth) => ("veryfast", Some("stillimage")),
+        };
+
+        let (max_fps, color_depth, tile_size) = match self {
+            EncoderProfile::LowBandwidth => (Some(5), ColorDepth::Palette256, LOW_BANDWIDTH_TILE_SIZE),
+            _ => (None, ColorDepth::Full, DEFAULT_TILE_SIZE),
+        };
+
+        EncoderSettings {
+            profile: *self,
+            family,
+            gop_seconds,
+            b_frames,
+            rate_control,
+            preset,
+            tune,
+            jpeg_quality,
+            lossless,
+            h264_profile: None,
+            max_fps,
+            color_depth,
+            tile_size,
+        }
+    }
+}
+
+impl std::fmt::Display for EncoderProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for EncoderProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "low-latency" => Ok(EncoderProfile::LowLatency),
+            "balanced" => Ok(EncoderProfile::Balanced),
+            "quality" => Ok(EncoderProfile::Quality),
+            "archival" => Ok(EncoderProfile::Archival),
+            "low-bandwidth" => Ok(EncoderProfile::LowBandwidth),
+            other => Err(anyhow::anyhow!("Unknown encoder profile: {}", other)),
+        }
+    }
+}
+
+/// Encoder families whose option names differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecFamily {
+    /// Built-in JPEG/PNG encoder
+    Software,
+    /// libx264 through FFmpeg
+    X264,
+    /// libx265 through FFmpeg
+    X265,
+    /// NVIDIA NVENC
+    Nvenc,
+}
+
+/// H.264 profile, for viewers whose decoder handles only part of H.264
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum H264Profile {
+    /// No B-frames or CABAC; decodes everywhere
+    Baseline,
+    Main,
+    High,
+}
+
+impl H264Profile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            H264Profile::Baseline => "baseline",
+            H264Profile::Main => "main",
+            H264Profile::High => "high",
+        }
+    }
+}
+
+impl std::fmt::Display for H264Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Rate control mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RateControl {
+    /// Constant bitrate at the target, for a steady network load
+    Cbr,
+    /// Constant quality, capped at the target bitrate
+    CappedCrf(u8),
+    /// Constant quality with no bitrate cap
+    Crf(u8),
+}
+
+/// Encoder parameters produced by a profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderSettings {
+    pub profile: EncoderProfile,
+    pub family: CodecFamily,
+    /// Keyframe interval in seconds
+    pub gop_seconds: u32,
+    pub b_frames: u32,
+    pub rate_control: RateControl,
+    pub preset: &'static str,
+    pub tune: Option<&'static str>,
+    /// JPEG quality for the software encoder
+    pub jpeg_quality: u8,
+    /// Whether the software encoder should use lossless PNG
+    pub lossless: bool,
+    /// H.264 profile to hold the encoder to; `None` leaves it to the encoder
+    pub h264_profile: Option<H264Profile>,
+    /// Frame rate the capture loop may not exceed; `None` leaves it to the
+    /// adaptive controller
+    pub max_fps: Option<u32>,
+    /// Color frames are reduced to before encoding
+    pub color_depth: ColorDepth,
+    /// Edge length of the frame differ's tiles
+    pub tile_size: u32,
+}
+
+impl EncoderSettings {
+    /// GOP length in frames at `fps`
+    pub fn gop_length(&self, fps: u32) -> u32 {
+        self.gop_seconds * fps.max(1)
+    }
+
+    /// These settings held to `h264_profile`, for a viewer that decodes no
+    /// more than that
+    pub fn with_h264_profile(mut self, h264_profile: Option<H264Profile>) -> Self {
+        if h264_profile == Some(H264Profile::Baseline) {
+            // Baseline has no B-frames
+            self.b_frames = 0;
+        }
+        self.h264_profile = h264_profile;
+        self
+    }
+
+    /// FFmpeg options to set before opening the encoder, in order
+    pub fn ffmpeg_options(&self, fps: u32, bitrate: u32) -> Vec<(&'static str, String)> {
+        let gop = self.gop_length(fps);
+        let mut options = vec![("preset", self.preset.to_string())];
+        if let Some(tune) = self.tune {
+            options.push(("tune", tune.to_string()));
+        }
+
+        if let Some(profile) = self.h264_profile {
+            options.push(("profile", profile.name().to_string()));
+        }
+
+        options.push(("g", gop.to_string()));
+        options.push(("bf", self.b_frames.to_string()));
+
+        match self.rate_control {
+            RateControl::Cbr => {
+                options.push(("b", bitrate.to_string()));
+                options.push(("maxrate", bitrate.to_string()));
+                options.push(("minrate", bitrate.to_string()));
+                // One second of buffer keeps the rate steady without adding latency
+                options.push(("bufsize", bitrate.to_string()));
+                if self.family == CodecFamily::X264 {
+                    options.push(("nal-hrd", "cbr".to_string()));
+                }
+            }
+            RateControl::CappedCrf(crf) => {
+                options.push(("crf", crf.to_string()));
+                options.push(("maxrate", bitrate.to_string()));
+                options.push(("bufsize", (bitrate * 2).to_string()));
+            }
+            RateControl::Crf(crf) => {
+                options.push(("crf", crf.to_string()));
+            }
+        }
+
+        if self.family == CodecFamily::X265 {
+            options.push((
+                "x265-params",
+                format!("bframes={}:keyint={}:min-keyint={}:no-scenecut=1", self.b_frames, gop, gop),
+            ));
+        }
+
+        options
+    }
+}
+
+/// Longest a viewer waits for a keyframe unless the policy says otherwise
+pub const DEFAULT_MAX_KEYFRAME_INTERVAL_SECS: u64 = 10;
+
+/// Organization policy for encoder profiles
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodingPolicy {
+    /// Profile overrides per session type; unlisted types use the default mapping
+    #[serde(default)]
+    pub profiles: HashMap<SessionType, EncoderProfile>,
+    /// Frame rate and bitrate bounds for adaptive streaming
+    #[serde(default)]
+    pub adaptive: AdaptiveConfig,
+    /// Longest stretch without a keyframe, in seconds, so a viewer that lost
+    /// its reference frame recovers even if nobody asks for one
+    #[serde(default = "default_max_keyframe_interval_secs")]
+    pub max_keyframe_interval_secs: u64,
+    /// Encoder to use instead of the hardware fallback chain, for debugging;
+    /// the `GHOSTLINK_ENCODER` environment variable takes precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<EncoderKind>,
+    /// Bandwidth no session may exceed, in kbit/s, whatever its own cap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth_kbps: Option<u32>,
+}
+
+fn default_max_keyframe_interval_secs() -> u64 {
+    DEFAULT_MAX_KEYFRAME_INTERVAL_SECS
+}
+
+impl Default for EncodingPolicy {
+    fn default() -> Self {
+        Self {
+            profiles: HashMap::new(),
+            adaptive: AdaptiveConfig::default(),
+            max_keyframe_interval_secs: DEFAULT_MAX_KEYFRAME_INTERVAL_SECS,
+            encoder: None,
+            max_bandwidth_kbps: None,
+        }
+    }
+}
+
+impl EncodingPolicy {
+    /// Encoder forced by the environment or the policy, if any
+    pub fn forced_encoder(&self) -> Option<EncoderKind> {
+        match std::env::var(ENCODER_ENV) {
+            Ok(name) if !name.is_empty() => match name.parse() {
+                Ok(kind) => Some(kind),
+                Err(e) => {
+                    warn!("Ignoring {}: {}", ENCODER_ENV, e);
+                    self.encoder
+                }
+            },
+            _ => self.encoder,
+        }
+    }
+
+    /// Longest stretch without a keyframe; never shorter than a second
+    pub fn max_keyframe_interval(&self) -> Duration {
+        Duration::from_secs(self.max_keyframe_interval_secs.max(1))
+    }
+
+    /// Profile to use for a session type
+    pub fn profile_for(&self, session_type: SessionType) -> EncoderProfile {
+        self.profiles
+            .get(&session_type)
+            .copied()
+            .unwrap_or_else(|| EncoderProfile::for_session_type(session_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::encoding::{CompressionMode, SoftwareEncoder};
+    use crate::capture::h264_encoder::H264Encoder;
+    use crate::capture::VideoEncoder;
+
+    fn option<'a>(options: &'a [(&'static str, String)], key: &str) -> Option<&'a str> {
+        options.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_session_type_defaults_and_overrides() {
+        let mut policy = EncodingPolicy::default();
+        assert_eq!(policy.profile_for(SessionType::Console), EncoderProfile::LowLatency);
+        assert_eq!(policy.profile_for(SessionType::Backstage), EncoderProfile::Quality);
+        assert_eq!(policy.profile_for(SessionType::AdHoc), EncoderProfile::Balanced);
+
+        policy.profiles.insert(SessionType::Console, EncoderProfile::Quality);
+        assert_eq!(policy.profile_for(SessionType::Console), EncoderProfile::Quality);
+        assert_eq!("low_latency".parse::<EncoderProfile>().unwrap(), EncoderProfile::LowLatency);
+
+        // Configs from before the setting still get a recovery point
+        let policy: EncodingPolicy = serde_json::from_str("{}").unwrap();
+        assert_eq!(policy.max_keyframe_interval(), Duration::from_secs(DEFAULT_MAX_KEYFRAME_INTERVAL_SECS));
+    }
+
+    #[test]
+    fn test_h264_initialization_parameters() {
+        let expected = [
+            (EncoderProfile::LowLatency, "ultrafast", Some("zerolatency"), "60", "0", None),
+            (EncoderProfile::Balanced, "veryfast", Some("zerolatency"), "120", "0", Some("23")),
+            (EncoderProfile::Quality, "medium", Some("stillimage"), "240", "0", Some("18")),
+            (EncoderProfile::Archival, "slow", None, "600", "3", Some("20")),
+        ];
+
+        for (profile, preset, tune, gop, bf, crf) in expected {
+            let mut encoder = H264Encoder::new();
+            encoder.apply_profile(&profile.settings(CodecFamily::X264));
+
+            // A fresh encoder runs at 60fps and 2 Mbps
+            let options = encoder.initialization_options();
+            assert_eq!(option(&options, "preset"), Some(preset), "{}", profile);
+            assert_eq!(option(&options, "tune"), tune, "{}", profile);
+            assert_eq!(option(&options, "g"), Some(gop), "{}", profile);
+            assert_eq!(option(&options, "bf"), Some(bf), "{}", profile);
+            assert_eq!(option(&options, "crf"), crf, "{}", profile);
+            assert_eq!(encoder.get_encoder_info().profile, Some(profile));
+        }
+
+        let mut encoder = H264Encoder::new();
+        encoder.apply_profile(&EncoderProfile::LowLatency.settings(CodecFamily::X264));
+        let options = encoder.initialization_options();
+        assert_eq!(option(&options, "nal-hrd"), Some("cbr"));
+        assert_eq!(option(&options, "maxrate"), Some("2000000"));
+        assert_eq!(option(&options, "minrate"), Some("2000000"));
+        assert_eq!(option(&options, "profile"), None);
+
+        // Baseline rules out B-frames, even for archival
+        let settings = EncoderProfile::Archival.settings(CodecFamily::X264).with_h264_profile(Some(H264Profile::Baseline));
+        encoder.apply_profile(&settings);
+        let options = encoder.initialization_options();
+        assert_eq!(option(&options, "profile"), Some("baseline"));
+        assert_eq!(option(&options, "bf"), Some("0"));
+    }
+
+    #[tokio::test]
+    async fn test_software_initialization_parameters() {
+        let expected = [
+            (EncoderProfile::LowLatency, 70, CompressionMode::Jpeg),
+            (EncoderProfile::Balanced, 80, CompressionMode::Jpeg),
+            (EncoderProfile::Quality, 95, CompressionMode::Jpeg),
+            (EncoderProfile::Archival, 100, CompressionMode::Png),
+            (EncoderProfile::LowBandwidth, 40, CompressionMode::Jpeg),
+        ];
+
+        for (profile, quality, mode) in expected {
+            let mut encoder = SoftwareEncoder::new().await.unwrap();
+            encoder.apply_profile(&profile.settings(CodecFamily::Software));
+            assert_eq!(encoder.jpeg_quality(), quality, "{}", profile);
+            assert_eq!(encoder.compression_mode(), mode, "{}", profile);
+            assert_eq!(encoder.get_encoder_info().profile, Some(profile));
+        }
+    }
+
+    #[test]
+    fn test_low_bandwidth_preset() {
+        let settings = EncoderProfile::LowBandwidth.settings(CodecFamily::X264);
+        assert_eq!(settings.max_fps, Some(5));
+        assert_eq!(settings.color_depth, ColorDepth::Palette256);
+        assert_eq!(settings.tile_size, 32);
+        assert_eq!("low-bandwidth".parse::<EncoderProfile>().unwrap(), EncoderProfile::LowBandwidth);
+
+        let mut encoder = H264Encoder::new();
+        encoder.apply_profile(&settings);
+        let options = encoder.initialization_options();
+        assert_eq!(option(&options, "crf"), Some("32"));
+        assert_eq!(option(&options, "tune"), Some("stillimage"));
+        assert_eq!(option(&options, "bf"), Some("0"));
+        // Capped at the target bitrate, so the bandwidth cap holds
+        assert_eq!(option(&options, "maxrate"), Some("2000000"));
+
+        // The other profiles leave frame rate and color alone
+        let balanced = EncoderProfile::Balanced.settings(CodecFamily::Software);
+        assert_eq!(balanced.max_fps, None);
+        assert_eq!(balanced.color_depth, ColorDepth::Full);
+        assert_eq!(balanced.tile_size, DEFAULT_TILE_SIZE);
+    }
+
+    #[test]
+    fn test_nvenc_and_x265_presets() {
+        let nvenc = EncoderProfile::LowLatency.settings(CodecFamily::Nvenc);
+        assert_eq!((nvenc.preset, nvenc.tune), ("p1", Some("ull")));
+
+        let options = EncoderProfile::Archival.settings(CodecFamily::X265).ffmpeg_options(30, 2_000_000);
+        assert_eq!(
+            option(&options, "x265-params"),
+            Some("bframes=3:keyint=300:min-keyint=300:no-scenecut=1")
+        );
+    }
+}
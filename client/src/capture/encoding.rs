@@ -1,10 +1,21 @@
 #![allow(dead_code)]
 
 use crate::error::{GhostLinkError, Result};
-use tracing::{debug, info, warn};
+use tracing::{debug, info};
 
+use super::encoder_factory::EncoderFactory;
+use super::encoder_profile::{EncoderProfile, EncoderSettings};
+use super::frame_diff::DirtyRect;
+use super::frame_protocol::VideoCodec;
+use super::tiles::TileFrame;
 use super::{EncoderInfo, Frame, PixelFormat, VideoEncoder, VideoEncoderEnum};
 
+/// JPEG quality change per frame while steering toward a target bitrate
+const JPEG_QUALITY_STEP: u8 = 5;
+
+/// Lowest JPEG quality bitrate steering goes down to
+const MIN_STEERED_JPEG_QUALITY: u8 = 20;
+
 /// Compression mode for software encoder
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CompressionMode {
@@ -16,35 +27,8 @@ pub enum CompressionMode {
 
 /// Create the best available video encoder for the platform
 pub async fn create_best_encoder() -> Result<VideoEncoderEnum> {
-    // Try hardware encoders first, then fall back to software
-
-    #[cfg(feature = "nvenc")]
-    {
-        use crate::capture::nvenc_encoder::{NvencEncoder, NvencCodec};
-        let encoder = NvencEncoder::new(NvencCodec::H264);
-        info!("Using NVIDIA NVENC H.264 hardware encoder");
-        return Ok(VideoEncoderEnum::NvencH264(encoder));
-    }
-
-    #[cfg(feature = "qsv")]
-    {
-        if let Ok(encoder) = QsvEncoder::new().await {
-            info!("Using Intel Quick Sync Video encoder");
-            return Ok(VideoEncoderEnum::Qsv(encoder));
-        }
-    }
-
-    #[cfg(feature = "videotoolbox")]
-    {
-        if let Ok(encoder) = VideoToolboxEncoder::new().await {
-            info!("Using Apple VideoToolbox encoder");
-            return Ok(VideoEncoderEnum::VideoToolbox(encoder));
-        }
-    }
-
-    // Fallback to software encoder with JPEG (faster than PNG)
-    warn!("No hardware encoder available, using software JPEG encoder");
-    Ok(VideoEncoderEnum::Software(SoftwareEncoder::new().await?))
+    // Hardware encoders first, each tried on a dummy frame, then software
+    Ok(EncoderFactory::create_probed(None).await?.encoder)
 }
 
 /// Software video encoder (fallback) with JPEG and PNG support
@@ -56,6 +40,19 @@ pub struct SoftwareEncoder {
     is_initialized: bool,
     compression_mode: CompressionMode,
     jpeg_quality: u8,
+    /// Quality the profile asks for; bitrate steering never goes above it
+    max_jpeg_quality: u8,
+    /// Bitrate hint from the adaptive controller, in kbit/s
+    target_kbps: Option<u32>,
+    profile: Option<EncoderProfile>,
+    /// Send only the changed regions as tile frames, for viewers that
+    /// composite them
+    tile_mode: bool,
+    /// Regions changed since the previous frame, from the capture loop
+    dirty_regions: Option<Vec<DirtyRect>>,
+    /// The next tile frame covers the whole picture
+    full_frame_due: bool,
+    last_frame_full: bool,
 }
 
 impl SoftwareEncoder {
@@ -67,6 +64,13 @@ impl SoftwareEncoder {
             is_initialized: false,
             compression_mode: CompressionMode::Jpeg, // Default to JPEG for speed
             jpeg_quality: 80, // Good balance of quality and speed
+            max_jpeg_quality: 80,
+            target_kbps: None,
+            profile: None,
+            tile_mode: false,
+            dirty_regions: None,
+            full_frame_due: true,
+            last_frame_full: true,
         })
     }
 
@@ -82,6 +86,55 @@ impl SoftwareEncoder {
         debug!("JPEG quality set to {}", self.jpeg_quality);
     }
 
+    /// Send tile frames of the changed regions instead of whole pictures;
+    /// either way the next frame is complete
+    pub fn set_tile_mode(&mut self, enabled: bool) {
+        if enabled != self.tile_mode {
+            info!("Software encoder tile mode {}", if enabled { "on" } else { "off" });
+        }
+        self.tile_mode = enabled;
+        self.dirty_regions = None;
+        self.full_frame_due = true;
+    }
+
+    /// Codec of the frames this encoder currently produces
+    pub fn frame_codec(&self) -> VideoCodec {
+        match (self.tile_mode, self.compression_mode) {
+            (true, _) => VideoCodec::Tiles,
+            (false, CompressionMode::Jpeg) => VideoCodec::Jpeg,
+            (false, CompressionMode::Png) => VideoCodec::Png,
+        }
+    }
+
+    /// Current compression mode
+    pub fn compression_mode(&self) -> CompressionMode {
+        self.compression_mode
+    }
+
+    /// Current JPEG quality
+    pub fn jpeg_quality(&self) -> u8 {
+        self.jpeg_quality
+    }
+
+    /// Nudge JPEG quality so frames of `encoded_len` bytes fit the target bitrate
+    fn steer_jpeg_quality(&mut self, encoded_len: usize) {
+        let Some(kbps) = self.target_kbps else {
+            return;
+        };
+        let frame_budget = kbps as usize * 1000 / 8 / self.fps.max(1) as usize;
+
+        let quality = if encoded_len > frame_budget {
+            self.jpeg_quality.saturating_sub(JPEG_QUALITY_STEP).max(MIN_STEERED_JPEG_QUALITY)
+        } else if encoded_len < frame_budget * 3 / 4 {
+            (self.jpeg_quality + JPEG_QUALITY_STEP).min(self.max_jpeg_quality)
+        } else {
+            return;
+        };
+        if quality != self.jpeg_quality {
+            self.set_jpeg_quality(quality);
+        }
+    }
+
     /// Fast JPEG compression for real-time streaming
     fn compress_jpeg(&self, frame: &Frame) -> Result<Vec<u8>> {
         use image::{ImageBuffer, RgbaImage};
@@ -94,11 +147,12 @@ impl SoftwareEncoder {
         // Convert to RGB (JPEG doesn't support alpha)
         let rgb_img = image::DynamicImage::ImageRgba8(img).to_rgb8();
 
-        // Encode to JPEG
+        // Encode to JPEG at the configured quality
         let mut jpeg_data = Vec::new();
         let mut cursor = Cursor::new(&mut jpeg_data);
 
-        rgb_img.write_to(&mut cursor, image::ImageFormat::Jpeg)
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut cursor, self.jpeg_quality)
+            .encode(&rgb_img, rgb_img.width(), rgb_img.height(), image::ColorType::Rgb8)
             .map_err(|e| GhostLinkError::Encode(format!("JPEG encoding failed: {}", e)))?;
 
         debug!("JPEG encoded {}x{} -> {} bytes (quality {})",
@@ -107,6 +161,30 @@ impl SoftwareEncoder {
         Ok(jpeg_data)
     }
 
+    /// The regions changed since the previous frame as a tile frame, or the
+    /// whole picture when a full frame is due
+    fn encode_tiles(&mut self, frame: &Frame) -> Result<Vec<u8>> {
+        let full = DirtyRect { x: 0, y: 0, width: frame.width, height: frame.height };
+        let regions = match self.dirty_regions.take() {
+            Some(regions) if !self.full_frame_due => regions,
+            _ => vec![full],
+        };
+        let lossless = self.compression_mode == CompressionMode::Png;
+
+        // Should encoding fail, the viewer misses these changes
+        self.full_frame_due = true;
+        let tiles = TileFrame::encode_regions(frame, &regions, self.jpeg_quality, lossless)?;
+        self.full_frame_due = false;
+        self.last_frame_full = regions == [full];
+
+        let data = tiles.to_bytes();
+        debug!("Tile frame of {} tiles -> {} bytes (quality {})", tiles.tiles.len(), data.len(), self.jpeg_quality);
+        if !lossless {
+            self.steer_jpeg_quality(data.len());
+        }
+        Ok(data)
+    }
+
     /// Lossless PNG compression (slower but preserves quality)
     fn compress_png(&self, frame: &Frame) -> Result<Vec<u8>> {
         use std::io::Cursor;
@@ -140,6 +218,8 @@ impl VideoEncoder for SoftwareEncoder {
         self.height = height;
         self.fps = fps;
         self.is_initialized = true;
+        self.dirty_regions = None;
+        self.full_frame_due = true;
 
         Ok(())
     }
@@ -149,15 +229,24 @@ impl VideoEncoder for SoftwareEncoder {
             return Err(GhostLinkError::Encode("Encoder not initialized".to_string()));
         }
 
+        if self.tile_mode {
+            return self.encode_tiles(frame);
+        }
+
         // Use configured compression mode (JPEG by default for real-time)
         match self.compression_mode {
-            CompressionMode::Jpeg => self.compress_jpeg(frame),
+            CompressionMode::Jpeg => {
+                let jpeg_data = self.compress_jpeg(frame)?;
+                self.steer_jpeg_quality(jpeg_data.len());
+                Ok(jpeg_data)
+            }
             CompressionMode::Png => self.compress_png(frame),
         }
     }
 
     fn get_encoder_info(&self) -> EncoderInfo {
         let name = match self.compression_mode {
+            _ if self.tile_mode => format!("Software Tile Encoder (q={})", self.jpeg_quality),
             CompressionMode::Jpeg => format!("Software JPEG Encoder (q={})", self.jpeg_quality),
             CompressionMode::Png => "Software PNG Encoder".to_string(),
         };
@@ -172,12 +261,43 @@ impl VideoEncoder for SoftwareEncoder {
                 PixelFormat::BGR,
             ],
             max_resolution: (4096, 4096),
+            profile: self.profile,
+            skipped: Vec::new(),
         }
     }
 
     fn is_healthy(&self) -> bool {
         self.is_initialized
     }
+
+    fn apply_profile(&mut self, settings: &EncoderSettings) {
+        let mode = if settings.lossless { CompressionMode::Png } else { CompressionMode::Jpeg };
+        self.set_compression_mode(mode);
+        self.set_jpeg_quality(settings.jpeg_quality);
+        self.max_jpeg_quality = self.jpeg_quality;
+        self.profile = Some(settings.profile);
+    }
+
+    fn set_bitrate(&mut self, kbps: u32) {
+        // PNG is lossless and has nothing to trade against the bitrate
+        self.target_kbps = Some(kbps);
+    }
+
+    fn set_dirty_regions(&mut self, regions: &[DirtyRect]) {
+        if self.tile_mode {
+            self.dirty_regions = Some(regions.to_vec());
+        }
+    }
+
+    fn force_keyframe(&mut self) {
+        // Every JPEG or PNG frame decodes on its own already; tile frames
+        // need one covering the whole picture
+        self.full_frame_due = true;
+    }
+
+    fn last_frame_was_keyframe(&self) -> bool {
+        !self.tile_mode || self.last_frame_full
+    }
 }
 
 // Hardware encoder stubs (would be implemented with proper codec libraries)
@@ -0,0 +1,380 @@
+//! Dirty-region detection between consecutive captured frames
+//!
+//! Each frame is split into square tiles whose pixel rows are hashed with
+//! xxh3. A frame whose tile hashes all match the previous frame is skipped;
+//! otherwise the changed tiles are merged into rectangles the encoder may use.
+//! Capturers that know what changed, such as X11 with XDamage, pass their
+//! damage along and only the tiles under it are hashed again. A full refresh
+//! is forced periodically and on request (e.g. when a viewer joins), so a
+//! viewer never waits long for a complete picture.
+
+use std::time::{Duration, Instant};
+use xxhash_rust::xxh3::Xxh3;
+
+use super::{Frame, PixelFormat};
+
+/// Edge length of a tile in pixels
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+
+/// Rectangle of changed pixels, in frame coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What changed since the previous frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameChange {
+    /// Identical to the previous frame; nothing to encode
+    Unchanged,
+    /// Only these regions changed
+    Partial(Vec<DirtyRect>),
+    /// First frame, new geometry or format, or a forced refresh
+    Full,
+}
+
+/// Frame layout the stored hashes were computed for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    width: u32,
+    height: u32,
+    stride: usize,
+    pixel_format: PixelFormat,
+}
+
+/// Compares each frame against the previous one tile by tile
+#[derive(Debug)]
+pub struct FrameDiffer {
+    tile_size: u32,
+    refresh_interval: Duration,
+    layout: Option<Layout>,
+    tile_hashes: Vec<u64>,
+    /// Hash of the chroma planes of planar formats, which are not tiled
+    chroma_hash: u64,
+    last_full: Option<Instant>,
+    refresh_requested: bool,
+}
+
+impl FrameDiffer {
+    pub fn new(tile_size: u32, refresh_interval: Duration) -> Self {
+        Self {
+            tile_size: tile_size.max(1),
+            refresh_interval,
+            layout: None,
+            tile_hashes: Vec::new(),
+            chroma_hash: 0,
+            last_full: None,
+            refresh_requested: false,
+        }
+    }
+
+    /// Change the tile edge length; the next frame is sent in full, since
+    /// the stored hashes no longer line up
+    pub fn set_tile_size(&mut self, tile_size: u32) {
+        let tile_size = tile_size.max(1);
+        if tile_size != self.tile_size {
+            self.tile_size = tile_size;
+            self.layout = None;
+            self.tile_hashes.clear();
+        }
+    }
+
+    /// Treat the next frame as a full refresh
+    pub fn request_refresh(&mut self) {
+        self.refresh_requested = true;
+    }
+
+    /// Compare `frame` with the previous frame and remember it for the next call
+    pub fn diff(&mut self, frame: &Frame) -> FrameChange {
+        self.diff_at(frame, Instant::now())
+    }
+
+    /// Like [`Self::diff`], but trusting the capturer's `damage` for where
+    /// `frame` may differ: only the tiles under it are hashed again, and a
+    /// frame without damage is unchanged without reading its pixels
+    pub fn diff_damaged(&mut self, frame: &Frame, damage: &[DirtyRect]) -> FrameChange {
+        self.diff_damaged_at(frame, damage, Instant::now())
+    }
+
+    fn diff_at(&mut self, frame: &Frame, now: Instant) -> FrameChange {
+        let layout = layout(frame);
+        let (tile_hashes, chroma_hash) = self.hash_tiles(frame, layout);
+
+        let full = self.full_due(layout, now) || self.chroma_hash != chroma_hash;
+
+        let change = if full {
+            self.refresh_requested = false;
+            self.last_full = Some(now);
+            FrameChange::Full
+        } else {
+            let dirty = self.dirty_rects(frame, &tile_hashes);
+            if dirty.is_empty() {
+                FrameChange::Unchanged
+            } else {
+                FrameChange::Partial(dirty)
+            }
+        };
+
+        self.layout = Some(layout);
+        self.tile_hashes = tile_hashes;
+        self.chroma_hash = chroma_hash;
+        change
+    }
+
+    fn diff_damaged_at(&mut self, frame: &Frame, damage: &[DirtyRect], now: Instant) -> FrameChange {
+        let layout = layout(frame);
+        // Chroma planes are not tiled, so damage cannot narrow them down
+        if self.full_due(layout, now) || is_planar(frame.pixel_format) {
+            return self.diff_at(frame, now);
+        }
+        if damage.is_empty() {
+            return FrameChange::Unchanged;
+        }
+
+        let columns = self.columns(frame.width);
+        let mut tile_hashes = self.tile_hashes.clone();
+        for rect in damage {
+            if rect.width == 0 || rect.height == 0 || rect.x >= frame.width || rect.y >= frame.height {
+                continue;
+            }
+            let right = (rect.x + rect.width).min(frame.width) - 1;
+            let bottom = (rect.y + rect.height).min(frame.height) - 1;
+            for row in rect.y / self.tile_size..=bottom / self.tile_size {
+                for column in rect.x / self.tile_size..=right / self.tile_size {
+                    tile_hashes[(row * columns + column) as usize] = self.hash_tile(frame, layout, column, row);
+                }
+            }
+        }
+
+        // Damage is often reported for pixels that were redrawn unchanged
+        let dirty = self.dirty_rects(frame, &tile_hashes);
+        self.tile_hashes = tile_hashes;
+        if dirty.is_empty() {
+            FrameChange::Unchanged
+        } else {
+            FrameChange::Partial(dirty)
+        }
+    }
+
+    /// Whether the next frame must be a full refresh regardless of content
+    fn full_due(&self, layout: Layout, now: Instant) -> bool {
+        let refresh_due = self.last_full
+            .is_none_or(|last| now.duration_since(last) >= self.refresh_interval);
+        self.refresh_requested || refresh_due || self.layout != Some(layout)
+    }
+
+    fn columns(&self, width: u32) -> u32 {
+        width.div_ceil(self.tile_size)
+    }
+
+    /// Hash each tile of the packed pixels (or the luma plane of planar
+    /// formats), plus everything after the luma plane as one value
+    fn hash_tiles(&self, frame: &Frame, layout: Layout) -> (Vec<u64>, u64) {
+        let bpp = tiled_bytes_per_pixel(frame.pixel_format);
+        let columns = self.columns(frame.width);
+        let rows = frame.height.div_ceil(self.tile_size);
+        let mut hashers: Vec<Xxh3> = (0..columns * rows).map(|_| Xxh3::new()).collect();
+
+        for y in 0..frame.height {
+            let row_start = y as usize * layout.stride;
+            let tile_row = y / self.tile_size;
+            for column in 0..columns {
+                let x = column * self.tile_size;
+                let tile_width = self.tile_size.min(frame.width - x);
+                let start = row_start + x as usize * bpp;
+                let end = start + tile_width as usize * bpp;
+                // Short buffers hash as empty so they never match real content
+                let bytes = frame.data.get(start..end).unwrap_or(&[]);
+                hashers[(tile_row * columns + column) as usize].update(bytes);
+            }
+        }
+
+        let chroma_hash = if is_planar(frame.pixel_format) {
+            let luma_len = layout.stride * frame.height as usize;
+            xxhash_rust::xxh3::xxh3_64(frame.data.get(luma_len..).unwrap_or(&[]))
+        } else {
+            0
+        };
+
+        (hashers.iter().map(|hasher| hasher.digest()).collect(), chroma_hash)
+    }
+
+    /// Hash of one tile, equal to its entry from [`Self::hash_tiles`]
+    fn hash_tile(&self, frame: &Frame, layout: Layout, column: u32, row: u32) -> u64 {
+        let bpp = tiled_bytes_per_pixel(frame.pixel_format);
+        let x = column * self.tile_size;
+        let tile_width = self.tile_size.min(frame.width - x);
+        let mut hasher = Xxh3::new();
+        for y in row * self.tile_size..((row + 1) * self.tile_size).min(frame.height) {
+            let start = y as usize * layout.stride + x as usize * bpp;
+            let end = start + tile_width as usize * bpp;
+            hasher.update(frame.data.get(start..end).unwrap_or(&[]));
+        }
+        hasher.digest()
+    }
+
+    /// Merge horizontal runs of changed tiles into rectangles
+    fn dirty_rects(&self, frame: &Frame, tile_hashes: &[u64]) -> Vec<DirtyRect> {
+        let columns = self.columns(frame.width);
+        let mut rects = Vec::new();
+
+        for (row, (new, old)) in tile_hashes.chunks(columns as usize)
+            .zip(self.tile_hashes.chunks(columns as usize))
+            .enumerate()
+        {
+            let y = row as u32 * self.tile_size;
+            let height = self.tile_size.min(frame.height - y);
+            let mut run_start = None;
+
+            for column in 0..=columns as usize {
+                let changed = column < columns as usize && new[column] != old[column];
+                match (changed, run_start) {
+                    (true, None) => run_start = Some(column as u32),
+                    (false, Some(start)) => {
+                        let x = start * self.tile_size;
+                        let end = (column as u32 * self.tile_size).min(frame.width);
+                        rects.push(DirtyRect { x, y, width: end - x, height });
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        rects
+    }
+}
+
+fn layout(frame: &Frame) -> Layout {
+    Layout {
+        width: frame.width,
+        height: frame.height,
+        stride: row_stride(frame),
+        pixel_format: frame.pixel_format,
+    }
+}
+
+/// Bytes per pixel in the tiled plane
+fn tiled_bytes_per_pixel(format: PixelFormat) -> usize {
+    match format {
+        PixelFormat::RGBA | PixelFormat::BGRA => 4,
+        PixelFormat::RGB | PixelFormat::BGR => 3,
+        PixelFormat::YUV420 | PixelFormat::NV12 => 1,
+    }
+}
+
+fn is_planar(format: PixelFormat) -> bool {
+    matches!(format, PixelFormat::YUV420 | PixelFormat::NV12)
+}
+
+/// Bytes per row, falling back to tightly packed rows when the capturer
+/// leaves the stride unset
+fn row_stride(frame: &Frame) -> usize {
+    let packed = frame.width as usize * tiled_bytes_per_pixel(frame.pixel_format);
+    (frame.stride as usize).max(packed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFRESH: Duration = Duration::from_secs(10);
+
+    /// RGBA frame with `padding` unused bytes at the end of each row
+    fn frame(width: u32, height: u32, padding: u32) -> Frame {
+        let stride = width * 4 + padding;
+        Frame {
+            data: vec![0; (stride * height) as usize],
+            width,
+            height,
+            pixel_format: PixelFormat::RGBA,
+            stride,
+            timestamp: 0,
+        }
+    }
+
+    fn set_pixel(frame: &mut Frame, x: u32, y: u32) {
+        let offset = (y * frame.stride + x * 4) as usize;
+        frame.data[offset..offset + 4].copy_from_slice(&[255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_identical_frames_are_unchanged() {
+        let mut differ = FrameDiffer::new(DEFAULT_TILE_SIZE, REFRESH);
+        let now = Instant::now();
+        let frame = frame(200, 100, 0);
+
+        assert_eq!(differ.diff_at(&frame, now), FrameChange::Full);
+        assert_eq!(differ.diff_at(&frame, now + Duration::from_secs(1)), FrameChange::Unchanged);
+    }
+
+    #[test]
+    fn test_partial_change_reports_clipped_tiles() {
+        let mut differ = FrameDiffer::new(DEFAULT_TILE_SIZE, REFRESH);
+        let now = Instant::now();
+        let mut frame = frame(200, 100, 16);
+        differ.diff_at(&frame, now);
+
+        // Two adjacent tiles merge into one run; the edge tile is clipped to the frame
+        set_pixel(&mut frame, 70, 80);
+        set_pixel(&mut frame, 130, 99);
+        set_pixel(&mut frame, 199, 0);
+        assert_eq!(
+            differ.diff_at(&frame, now),
+            FrameChange::Partial(vec![
+                DirtyRect { x: 192, y: 0, width: 8, height: 64 },
+                DirtyRect { x: 64, y: 64, width: 128, height: 36 },
+            ])
+        );
+
+        // Row padding is not part of the picture
+        let padding = (frame.width * 4) as usize;
+        frame.data[padding] = 1;
+        assert_eq!(differ.diff_at(&frame, now), FrameChange::Unchanged);
+    }
+
+    #[test]
+    fn test_damage_narrows_the_comparison() {
+        let mut differ = FrameDiffer::new(DEFAULT_TILE_SIZE, REFRESH);
+        let now = Instant::now();
+        let mut frame = frame(200, 100, 0);
+        assert_eq!(differ.diff_damaged_at(&frame, &[], now), FrameChange::Full);
+
+        // Without damage the pixels are not even looked at
+        set_pixel(&mut frame, 10, 10);
+        assert_eq!(differ.diff_damaged_at(&frame, &[], now), FrameChange::Unchanged);
+
+        // Damage over the change finds it; the undamaged tile is not rehashed
+        set_pixel(&mut frame, 150, 70);
+        let damage = [DirtyRect { x: 140, y: 60, width: 20, height: 20 }];
+        assert_eq!(
+            differ.diff_damaged_at(&frame, &damage, now),
+            FrameChange::Partial(vec![DirtyRect { x: 128, y: 64, width: 64, height: 36 }])
+        );
+
+        // Redrawn but identical pixels are no change
+        assert_eq!(differ.diff_damaged_at(&frame, &damage, now), FrameChange::Unchanged);
+
+        // Refreshes still come in full
+        differ.request_refresh();
+        assert_eq!(differ.diff_damaged_at(&frame, &[], now), FrameChange::Full);
+    }
+
+    #[test]
+    fn test_resize_and_refresh_force_full_frames() {
+        let mut differ = FrameDiffer::new(DEFAULT_TILE_SIZE, REFRESH);
+        let now = Instant::now();
+        differ.diff_at(&frame(200, 100, 0), now);
+
+        assert_eq!(differ.diff_at(&frame(100, 200, 0), now), FrameChange::Full);
+        assert_eq!(differ.diff_at(&frame(100, 200, 0), now), FrameChange::Unchanged);
+
+        differ.request_refresh();
+        assert_eq!(differ.diff_at(&frame(100, 200, 0), now), FrameChange::Full);
+
+        assert_eq!(differ.diff_at(&frame(100, 200, 0), now + REFRESH), FrameChange::Full);
+    }
+}
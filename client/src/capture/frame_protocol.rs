@@ -1,9 +1,16 @@
+use crate::capture::encoder_profile::EncoderProfile;
 use crate::error::{GhostLinkError, Result};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
 const FRAME_HEADER_MAGIC: u32 = 0x47464D45; // "GFME" - GhostLink Frame Message
-const PROTOCOL_VERSION: u16 = 1;
+
+/// Frame header version this agent writes.
+///
+/// From version 2 on the header only ever grows: new fields go after the
+/// ones below and raise `header_size`, so a reader skips what it does not
+/// know yet. Version 1 headers left `header_size` zero.
+pub const PROTOCOL_VERSION: u16 = 2;
 
 /// Video frame format supported by the protocol
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
@@ -24,6 +31,9 @@ pub enum VideoCodec {
     NvencH265,
     /// NVIDIA NVENC AV1 (latest GPUs)
     NvencAV1,
+    /// Changed regions as JPEG, PNG or solid tiles, painted over the
+    /// previous picture
+    Tiles,
 }
 
 /// Frame quality level for adaptive streaming
@@ -69,8 +79,10 @@ pub struct FrameHeader {
     flags: u16,
     /// CRC32 checksum of data
     checksum: u32,
+    /// Bytes from the start of the header to the frame data
+    header_size: u16,
     /// Reserved for future use
-    reserved: [u8; 8],
+    reserved: [u8; 6],
 }
 
 /// Frame flags
@@ -78,6 +90,8 @@ pub const FLAG_KEYFRAME: u16 = 0x0001;
 pub const FLAG_DELTA: u16 = 0x0002;
 pub const FLAG_COMPRESSED: u16 = 0x0004;
 pub const FLAG_ERROR_CORRECTION: u16 = 0x0008;
+/// The cursor is drawn into the frame; viewers skip their own cursor overlay
+pub const FLAG_CURSOR_INCLUDED: u16 = 0x0010;
 
 impl FrameHeader {
     /// Create a new frame header
@@ -97,7 +111,8 @@ impl FrameHeader {
         // Mark compressed codecs
         match codec {
             VideoCodec::Jpeg | VideoCodec::H264 | VideoCodec::H265 |
-            VideoCodec::NvencH264 | VideoCodec::NvencH265 | VideoCodec::NvencAV1 => {
+            VideoCodec::NvencH264 | VideoCodec::NvencH265 | VideoCodec::NvencAV1 |
+            VideoCodec::Tiles => {
                 flags |= FLAG_COMPRESSED;
             }
             _ => {}
@@ -116,7 +131,8 @@ impl FrameHeader {
             timestamp,
             flags,
             checksum: 0, // Set by serialize_binary
-            reserved: [0; 8],
+            header_size: Self::size() as u16,
+            reserved: [0; 6],
         }
     }
     
@@ -135,15 +151,37 @@ impl FrameHeader {
         }
         
         let version = self.version;
-        if version != PROTOCOL_VERSION {
+        if version == 0 {
             return Err(GhostLinkError::Protocol(
                 format!("Unsupported protocol version: {}", version)
             ));
         }
         
+        let header_size = self.header_size();
+        if header_size < Self::size() {
+            return Err(GhostLinkError::Protocol(
+                format!("Frame header too small for version {}: {} bytes", version, header_size)
+            ));
+        }
+        
         Ok(())
     }
     
+    /// Protocol version of the sender
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+    
+    /// Bytes the sender's header takes up, including fields newer than
+    /// this build knows
+    pub fn header_size(&self) -> usize {
+        match self.header_size {
+            // Version 1 had no size field
+            0 if self.version == 1 => Self::size(),
+            size => size as usize,
+        }
+    }
+    
     /// Get codec from header
     pub fn get_codec(&self) -> Result<VideoCodec> {
         match self.codec {
@@ -155,6 +193,7 @@ impl FrameHeader {
             5 => Ok(VideoCodec::NvencH264),
             6 => Ok(VideoCodec::NvencH265),
             7 => Ok(VideoCodec::NvencAV1),
+            8 => Ok(VideoCodec::Tiles),
             _ => Err(GhostLinkError::Protocol(
                 format!("Unknown codec: {}", self.codec)
             )),
@@ -184,6 +223,20 @@ impl FrameHeader {
     pub fn is_compressed(&self) -> bool {
         (self.flags & FLAG_COMPRESSED) != 0
     }
+    
+    /// Check if the cursor is part of the frame image
+    pub fn cursor_included(&self) -> bool {
+        (self.flags & FLAG_CURSOR_INCLUDED) != 0
+    }
+    
+    /// Mark whether the capturer drew the cursor into the frame
+    pub fn set_cursor_included(&mut self, included: bool) {
+        if included {
+            self.flags |= FLAG_CURSOR_INCLUDED;
+        } else {
+            self.flags &= !FLAG_CURSOR_INCLUDED;
+        }
+    }
 }
 
 /// Complete frame message with header and data
@@ -225,6 +278,8 @@ impl FrameMessage {
     pub fn serialize_binary(&mut self) -> Result<Vec<u8>> {
         // Calculate CRC32 of data
         self.header.checksum = crc32fast::hash(&self.data);
+        // Fields of a newer sender are not written back out
+        self.header.header_size = FrameHeader::size() as u16;
         
         let total_size = FrameHeader::size() + self.data.len();
         let mut buffer = Vec::with_capacity(total_size);
@@ -271,8 +326,10 @@ impl FrameMessage {
         // Validate header
         header.validate()?;
         
-        // Check data size
-        let expected_size = FrameHeader::size() + header.data_size as usize;
+        // Check data size; fields of newer versions sit between the header
+        // and the data
+        let header_size = header.header_size();
+        let expected_size = header_size + header.data_size as usize;
         if data.len() != expected_size {
             return Err(GhostLinkError::Protocol(
                 format!("Frame size mismatch: expected {}, got {}", 
@@ -281,7 +338,7 @@ impl FrameMessage {
         }
         
         // Extract frame data
-        let frame_data = data[FrameHeader::size()..].to_vec();
+        let frame_data = data[header_size..].to_vec();
         
         // Verify checksum
         let calculated_checksum = crc32fast::hash(&frame_data);
@@ -351,6 +408,8 @@ pub struct FrameStats {
     pub checksum_errors: u64,
     pub last_sequence: u32,
     pub missed_frames: u64,
+    /// Encoder profile the frames were produced with
+    pub encoder_profile: Option<EncoderProfile>,
 }
 
 impl FrameStats {
@@ -449,6 +508,24 @@ mod tests {
         assert_eq!(decoded.header.is_keyframe(), true);
     }
     
+    #[test]
+    fn test_cursor_included_flag() {
+        let mut frame = FrameMessage::new(
+            7, &[0; 8], VideoCodec::Jpeg, QualityLevel::High, 640, 480, vec![1, 2, 3], 0, true,
+        );
+        assert!(!frame.header.cursor_included());
+        
+        frame.header.set_cursor_included(true);
+        let decoded = FrameMessage::deserialize_binary(&frame.serialize_binary().unwrap()).unwrap();
+        assert!(decoded.header.cursor_included());
+        // The flag sits beside the others without disturbing them
+        assert!(decoded.header.is_keyframe() && decoded.header.is_compressed());
+        
+        frame.header.set_cursor_included(false);
+        let decoded = FrameMessage::deserialize_binary(&frame.serialize_binary().unwrap()).unwrap();
+        assert!(!decoded.header.cursor_included());
+    }
+    
     #[test]
     fn test_frame_checksum_validation() {
         let session_id = [1, 2, 3, 4, 5, 6, 7, 8];
@@ -476,4 +553,46 @@ mod tests {
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("checksum mismatch"));
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_newer_header_fields_are_skipped() {
+        let mut frame = FrameMessage::new(
+            7,
+            &[9; 8],
+            VideoCodec::H264,
+            QualityLevel::Low,
+            640,
+            480,
+            vec![1, 2, 3],
+            0,
+            true,
+        );
+        let binary = frame.serialize_binary().unwrap();
+        let size = FrameHeader::size();
+        let version_at = std::mem::offset_of!(FrameHeader, version);
+        let header_size_at = std::mem::offset_of!(FrameHeader, header_size);
+        
+        // A version 3 sender appended four bytes of fields to its header
+        let mut newer = binary[..size].to_vec();
+        newer[version_at..version_at + 2].copy_from_slice(&3u16.to_ne_bytes());
+        newer[header_size_at..header_size_at + 2].copy_from_slice(&(size as u16 + 4).to_ne_bytes());
+        newer.extend_from_slice(&[0xEE; 4]);
+        newer.extend_from_slice(&binary[size..]);
+        let decoded = FrameMessage::deserialize_binary(&newer).unwrap();
+        assert_eq!(decoded.header.version(), 3);
+        assert_eq!(decoded.header.get_codec().unwrap(), VideoCodec::H264);
+        assert_eq!(decoded.data, [1, 2, 3]);
+        
+        // Version 1 headers had no size field
+        let mut older = binary.clone();
+        older[version_at..version_at + 2].copy_from_slice(&1u16.to_ne_bytes());
+        older[header_size_at..header_size_at + 2].copy_from_slice(&0u16.to_ne_bytes());
+        assert_eq!(FrameMessage::deserialize_binary(&older).unwrap().data, [1, 2, 3]);
+        
+        // A size that cuts into the known fields is corrupt
+        let mut truncated = binary;
+        truncated[header_size_at..header_size_at + 2].copy_from_slice(&10u16.to_ne_bytes());
+        let error = FrameMessage::deserialize_binary(&truncated).unwrap_err();
+        assert!(error.to_string().contains("header too small"), "{}", error);
+    }
+}
@@ -2,9 +2,11 @@
 
 use crate::{
     capture::{
+        bandwidth::BandwidthLimiter,
         frame_protocol::{FrameMessage, VideoCodec, QualityLevel, FrameStats},
         encoder_factory::{EncoderFactory, EncoderPreference},
-        VideoEncoderEnum, VideoEncoder, ScreenCapturerEnum, Frame,
+        encoder_profile::EncoderProfile,
+        VideoEncoderEnum, VideoEncoder, ScreenCapturer, ScreenCapturerEnum, Frame,
     },
     connection::RelayConnection,
     error::{GhostLinkError, Result},
@@ -20,7 +22,7 @@ use tokio::{
     task::JoinHandle,
 };
 use tracing::{debug, error, info, warn, trace};
-use parking_lot::RwLock as ParkingRwLock;
+use parking_lot::{Mutex as ParkingMutex, RwLock as ParkingRwLock};
 
 const TARGET_FPS: u32 = 60;
 const FRAME_TIME_MS: u64 = 1000 / TARGET_FPS as u64;
@@ -28,6 +30,35 @@ const KEYFRAME_INTERVAL_SECONDS: u64 = 2;
 const ADAPTIVE_QUALITY_WINDOW: usize = 30; // Frames to average for quality adaptation
 const MAX_FRAME_SIZE: usize = 2 * 1024 * 1024; // 2MB max frame size
 
+/// When the stream owes its viewers a keyframe
+#[derive(Debug)]
+struct KeyframeSchedule {
+    max_interval: Duration,
+    last_keyframe: Option<Instant>,
+    requested: bool,
+}
+
+impl KeyframeSchedule {
+    fn new(max_interval: Duration) -> Self {
+        Self { max_interval, last_keyframe: None, requested: false }
+    }
+
+    /// Force the next frame to be a keyframe
+    fn request(&mut self) {
+        self.requested = true;
+    }
+
+    /// Whether the next frame must be a keyframe
+    fn due(&self) -> bool {
+        self.requested || self.last_keyframe.is_none_or(|last| last.elapsed() >= self.max_interval)
+    }
+
+    fn keyframe_sent(&mut self) {
+        self.requested = false;
+        self.last_keyframe = Some(Instant::now());
+    }
+}
+
 /// High-performance frame streaming service with adaptive quality
 pub struct FrameStreamingService {
     /// Session identifier
@@ -40,6 +71,8 @@ pub struct FrameStreamingService {
     encoder: Arc<RwLock<Option<VideoEncoderEnum>>>,
     /// Current encoder preference
     encoder_preference: Arc<RwLock<EncoderPreference>>,
+    /// Active encoder preset profile
+    encoder_profile: Arc<ParkingRwLock<EncoderProfile>>,
     /// Frame sequence counter
     sequence_counter: Arc<AtomicU32>,
     /// Streaming state
@@ -50,12 +83,14 @@ pub struct FrameStreamingService {
     stats: Arc<ParkingRwLock<FrameStats>>,
     /// Streaming task handle
     stream_task: Arc<Mutex<Option<JoinHandle<()>>>>,
-    /// Last keyframe time
-    last_keyframe: Arc<Mutex<Instant>>,
+    /// Keyframe requests and the max keyframe interval
+    keyframes: Arc<ParkingMutex<KeyframeSchedule>>,
     /// Target bitrate (for adaptive quality)
     target_bitrate: Arc<RwLock<u32>>,
     /// Recent frame sizes for adaptive quality
     recent_frame_sizes: Arc<Mutex<Vec<usize>>>,
+    /// Holds sent frames to the session's bandwidth cap
+    limiter: Arc<BandwidthLimiter>,
 }
 
 impl FrameStreamingService {
@@ -64,7 +99,7 @@ impl FrameStreamingService {
         session_id: [u8; 8],
         connection: Arc<RelayConnection>,
         capturer: ScreenCapturerEnum,
-        preference: EncoderPreference,
+        profile: EncoderProfile,
     ) -> Result<Self> {
         info!("Creating frame streaming service for session {:?}", session_id);
         
@@ -75,15 +110,17 @@ impl FrameStreamingService {
             connection,
             capturer,
             encoder: Arc::new(RwLock::new(None)),
-            encoder_preference: Arc::new(RwLock::new(preference)),
+            encoder_preference: Arc::new(RwLock::new(profile.encoder_preference())),
+            encoder_profile: Arc::new(ParkingRwLock::new(profile)),
             sequence_counter: Arc::new(AtomicU32::new(0)),
             is_streaming: Arc::new(AtomicBool::new(false)),
             current_quality: Arc::new(ParkingRwLock::new(QualityLevel::High)),
             stats: Arc::new(ParkingRwLock::new(FrameStats::default())),
             stream_task: Arc::new(Mutex::new(None)),
-            last_keyframe: Arc::new(Mutex::new(Instant::now())),
+            keyframes: Arc::new(ParkingMutex::new(KeyframeSchedule::new(Duration::from_secs(KEYFRAME_INTERVAL_SECONDS)))),
             target_bitrate: Arc::new(RwLock::new(3000)), // 3 Mbps default
             recent_frame_sizes: Arc::new(Mutex::new(Vec::new())),
+            limiter: Arc::new(BandwidthLimiter::new()),
         })
     }
     
@@ -103,9 +140,9 @@ impl FrameStreamingService {
         
         *self.target_bitrate.write().await = bitrate;
         
-        // Create best encoder for streaming
-        let _preference = *self.encoder_preference.read().await;
-        let mut encoder = EncoderFactory::create_streaming_encoder(bitrate, TARGET_FPS).await?;
+        // Create best encoder for the active profile
+        let profile = *self.encoder_profile.read();
+        let mut encoder = EncoderFactory::create_with_profile(profile, TARGET_FPS).await?;
 
         // Initialize encoder using the enum's initialize method
         encoder.initialize(width, height, TARGET_FPS).await?;
@@ -128,9 +165,13 @@ impl FrameStreamingService {
         self.is_streaming.store(true, Ordering::Relaxed);
         
         // Reset stats and state
-        *self.stats.write() = FrameStats::default();
+        *self.stats.write() = FrameStats {
+            encoder_profile: Some(*self.encoder_profile.read()),
+            ..FrameStats::default()
+        };
         self.sequence_counter.store(0, Ordering::Relaxed);
-        *self.last_keyframe.lock().await = Instant::now();
+        // Viewers can only start decoding at a keyframe
+        self.keyframes.lock().request();
         
         // Start streaming task
         let task = self.spawn_streaming_task().await;
@@ -175,10 +216,13 @@ impl FrameStreamingService {
         let sequence_counter = Arc::clone(&self.sequence_counter);
         let current_quality = Arc::clone(&self.current_quality);
         let stats = Arc::clone(&self.stats);
-        let last_keyframe = Arc::clone(&self.last_keyframe);
+        let keyframes = Arc::clone(&self.keyframes);
         let recent_frame_sizes = Arc::clone(&self.recent_frame_sizes);
+        let limiter = Arc::clone(&self.limiter);
         
         tokio::spawn(async move {
+            // Viewers draw their own cursor over frames that lack one
+            let cursor_in_frames = capturer.lock().await.cursor_in_frames();
             let mut frame_interval = interval(Duration::from_millis(FRAME_TIME_MS));
             frame_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
             
@@ -199,9 +243,9 @@ impl FrameStreamingService {
                     }
                 };
                 
-                // Encode frame
-                let encoded_data = match Self::encode_frame(&encoder, &frame).await {
-                    Ok(data) => data,
+                // Encode frame, as a keyframe if one is due
+                let (encoded_data, is_keyframe) = match Self::encode_frame(&encoder, &frame, &keyframes).await {
+                    Ok(encoded) => encoded,
                     Err(e) => {
                         error!("Frame encoding failed: {}", e);
                         continue;
@@ -213,16 +257,6 @@ impl FrameStreamingService {
                     continue;
                 }
                 
-                // Determine if keyframe
-                let should_keyframe = {
-                    let last_kf = last_keyframe.lock().await;
-                    last_kf.elapsed().as_secs() >= KEYFRAME_INTERVAL_SECONDS
-                };
-                
-                if should_keyframe {
-                    *last_keyframe.lock().await = Instant::now();
-                }
-                
                 // Get codec from encoder
                 let codec = Self::detect_codec(&encoder).await;
                 let quality = *current_quality.read();
@@ -243,8 +277,9 @@ impl FrameStreamingService {
                     frame.height,
                     encoded_data,
                     timestamp,
-                    should_keyframe,
+                    is_keyframe,
                 );
+                frame_msg.header.set_cursor_included(cursor_in_frames);
                 
                 // Serialize to binary
                 let binary_data = match frame_msg.serialize_binary() {
@@ -258,6 +293,9 @@ impl FrameStreamingService {
                 // Check frame size for quality adaptation
                 Self::update_frame_size_history(&recent_frame_sizes, binary_data.len()).await;
                 
+                // Never exceed the session's bandwidth cap, even on a keyframe
+                limiter.admit(binary_data.len()).await;
+                
                 // Send via WebSocket as binary message (more efficient than JSON)
                 if let Err(e) = connection.send_binary_frame(binary_data).await {
                     error!("Failed to send frame: {}", e);
@@ -290,13 +328,18 @@ impl FrameStreamingService {
         capturer_guard.capture_frame().await
     }
     
-    /// Encode a frame with the current encoder
-    async fn encode_frame(encoder: &Arc<RwLock<Option<VideoEncoderEnum>>>, frame: &Frame) -> Result<Vec<u8>> {
+    /// Encode a frame with the current encoder; returns the data and whether
+    /// it decodes on its own
+    async fn encode_frame(
+        encoder: &Arc<RwLock<Option<VideoEncoderEnum>>>,
+        frame: &Frame,
+        keyframes: &ParkingMutex<KeyframeSchedule>,
+    ) -> Result<(Vec<u8>, bool)> {
         let mut encoder_guard = encoder.write().await;
         let encoder = encoder_guard.as_mut()
             .ok_or_else(|| GhostLinkError::Other("No encoder initialized".to_string()))?;
 
-        encoder.encode_frame(frame).await
+        encode_with_schedule(encoder, frame, keyframes).await
     }
     
     /// Detect codec from encoder type
@@ -304,8 +347,7 @@ impl FrameStreamingService {
         let encoder_guard = encoder.read().await;
         if let Some(ref encoder) = *encoder_guard {
             match encoder {
-                // Software encoder uses JPEG by default for real-time streaming
-                VideoEncoderEnum::Software(_) => VideoCodec::Jpeg,
+                VideoEncoderEnum::Software(encoder) => encoder.frame_codec(),
                 VideoEncoderEnum::H264(_) => VideoCodec::H264,
                 VideoEncoderEnum::Hevc(_) => VideoCodec::H265,
                 #[cfg(feature = "nvenc")]
@@ -414,6 +456,45 @@ impl FrameStreamingService {
         Ok(())
     }
     
+    /// Active encoder profile
+    pub fn encoder_profile(&self) -> EncoderProfile {
+        *self.encoder_profile.read()
+    }
+    
+    /// Switch encoder profile, re-initializing the encoder in place.
+    ///
+    /// Used by the technician's quality command; the stream continues with a
+    /// keyframe under the new settings.
+    pub async fn set_encoder_profile(&self, profile: EncoderProfile) -> Result<()> {
+        let (width, height) = self.capturer.lock().await.get_resolution();
+        
+        if let Some(encoder) = self.encoder.write().await.as_mut() {
+            encoder.set_profile(profile);
+            encoder.initialize(width, height, TARGET_FPS).await?;
+        }
+        
+        let old_profile = std::mem::replace(&mut *self.encoder_profile.write(), profile);
+        *self.encoder_preference.write().await = profile.encoder_preference();
+        self.stats.write().encoder_profile = Some(profile);
+        
+        // Start the new settings on a keyframe
+        self.request_keyframe();
+        
+        info!("Encoder profile changed: {} -> {}", old_profile, profile);
+        Ok(())
+    }
+    
+    /// Encode the next frame as a keyframe, for a viewer that joined
+    /// mid-stream or lost its reference frame
+    pub fn request_keyframe(&self) {
+        self.keyframes.lock().request();
+    }
+    
+    /// Longest stretch without a keyframe
+    pub fn set_max_keyframe_interval(&self, interval: Duration) {
+        self.keyframes.lock().max_interval = interval;
+    }
+    
     /// Change quality level
     pub fn set_quality_level(&self, quality: QualityLevel) {
         let old_quality = *self.current_quality.read();
@@ -423,4 +504,138 @@ impl FrameStreamingService {
             info!("Quality level changed: {:?} -> {:?}", old_quality, quality);
         }
     }
-}
\ No newline at end of file
+
+    /// Cap the bandwidth frames are sent at, or lift the cap with `None`
+    pub fn set_bandwidth_cap(&self, kbps: Option<u32>) -> Option<u32> {
+        self.limiter.set_cap(kbps)
+    }
+}
+
+/// Encode `frame`, forcing a keyframe when `keyframes` says one is due, and
+/// report whether the output actually is one so the frame header tells the
+/// truth even about keyframes the encoder inserted on its own
+async fn encode_with_schedule<E: VideoEncoder>(
+    encoder: &mut E,
+    frame: &Frame,
+    keyframes: &ParkingMutex<KeyframeSchedule>,
+) -> Result<(Vec<u8>, bool)> {
+    if keyframes.lock().due() {
+        encoder.force_keyframe();
+    }
+    
+    let data = encoder.encode_frame(frame).await?;
+    // A buffering encoder keeps the forced keyframe pending
+    let is_keyframe = !data.is_empty() && encoder.last_frame_was_keyframe();
+    if is_keyframe {
+        keyframes.lock().keyframe_sent();
+    }
+    Ok((data, is_keyframe))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{EncoderInfo, PixelFormat};
+    use crate::capture::frame_protocol::FrameHeader;
+
+    /// Codes a keyframe first and when forced, deltas otherwise
+    struct DeltaEncoder {
+        pending_keyframe: bool,
+        last_keyframe: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl VideoEncoder for DeltaEncoder {
+        async fn initialize(&mut self, _width: u32, _height: u32, _fps: u32) -> Result<()> {
+            self.pending_keyframe = true;
+            Ok(())
+        }
+
+        async fn encode_frame(&mut self, _frame: &Frame) -> Result<Vec<u8>> {
+            self.last_keyframe = std::mem::take(&mut self.pending_keyframe);
+            Ok(vec![self.last_keyframe as u8; 16])
+        }
+
+        fn get_encoder_info(&self) -> EncoderInfo {
+            EncoderInfo {
+                name: "Delta".to_string(),
+                hardware_accelerated: false,
+                supported_formats: vec![PixelFormat::RGBA],
+                max_resolution: (8192, 8192),
+                profile: None,
+                skipped: Vec::new(),
+            }
+        }
+
+        fn is_healthy(&self) -> bool {
+            true
+        }
+
+        fn force_keyframe(&mut self) {
+            self.pending_keyframe = true;
+        }
+
+        fn last_frame_was_keyframe(&self) -> bool {
+            self.last_keyframe
+        }
+    }
+
+    fn frame() -> Frame {
+        Frame {
+            data: vec![0; 4 * 4 * 4],
+            width: 4,
+            height: 4,
+            pixel_format: PixelFormat::RGBA,
+            stride: 16,
+            timestamp: 0,
+        }
+    }
+
+    /// Header of the next frame as the viewer receives it
+    async fn send<E: VideoEncoder>(encoder: &mut E, keyframes: &ParkingMutex<KeyframeSchedule>, sequence: u32) -> FrameHeader {
+        let frame = frame();
+        let (data, is_keyframe) = encode_with_schedule(encoder, &frame, keyframes).await.unwrap();
+        let mut message = FrameMessage::new(
+            sequence,
+            &[1; 8],
+            VideoCodec::H264,
+            QualityLevel::High,
+            frame.width,
+            frame.height,
+            data,
+            frame.timestamp,
+            is_keyframe,
+        );
+        let binary = message.serialize_binary().unwrap();
+        FrameMessage::deserialize_binary(&binary).unwrap().header
+    }
+
+    #[tokio::test]
+    async fn test_forced_keyframe_is_flagged_in_header() {
+        let mut encoder = DeltaEncoder { pending_keyframe: false, last_keyframe: false };
+        encoder.initialize(4, 4, 30).await.unwrap();
+        let keyframes = ParkingMutex::new(KeyframeSchedule::new(Duration::from_secs(60)));
+
+        assert!(send(&mut encoder, &keyframes, 0).await.is_keyframe());
+        assert!(!send(&mut encoder, &keyframes, 1).await.is_keyframe());
+
+        // A viewer joined or failed to decode
+        keyframes.lock().request();
+        assert!(send(&mut encoder, &keyframes, 2).await.is_keyframe());
+        assert!(!send(&mut encoder, &keyframes, 3).await.is_keyframe());
+
+        // The header follows the encoder, not the request
+        encoder.force_keyframe();
+        assert!(send(&mut encoder, &keyframes, 4).await.is_keyframe());
+    }
+
+    #[tokio::test]
+    async fn test_max_keyframe_interval_forces_keyframes() {
+        let mut encoder = DeltaEncoder { pending_keyframe: false, last_keyframe: false };
+        let keyframes = ParkingMutex::new(KeyframeSchedule::new(Duration::ZERO));
+
+        for sequence in 0..3 {
+            assert!(send(&mut encoder, &keyframes, sequence).await.is_keyframe());
+        }
+    }
+}
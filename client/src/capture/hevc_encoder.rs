@@ -8,13 +8,12 @@ use tracing::{debug, info, warn};
 #[cfg(feature = "x264-encoder")]
 use ffmpeg_next as ffmpeg;
 
+use crate::capture::encoder_profile::{CodecFamily, EncoderProfile, EncoderSettings};
 use crate::capture::{EncoderInfo, Frame, PixelFormat, VideoEncoder};
 use crate::error::{GhostLinkError, Result};
 
 const TARGET_FPS: u32 = 60;
 const DEFAULT_BITRATE: u32 = 1_500_000; // 1.5 Mbps (lower than H.264 due to better compression)
-const DEFAULT_PRESET: &str = "ultrafast"; // For low latency
-const DEFAULT_TUNE: &str = "zerolatency"; // For real-time streaming
 
 /// High-performance H.265/HEVC encoder for 60fps with better compression than H.264
 pub struct HevcEncoder {
@@ -26,7 +25,10 @@ pub struct HevcEncoder {
     frame_count: u64,
     last_keyframe: u64,
     keyframe_interval: u64,
+    /// Whether the last packet out of the encoder was a keyframe
+    last_frame_keyframe: bool,
     is_initialized: bool,
+    settings: EncoderSettings,
 }
 
 struct EncoderContext {
@@ -54,7 +56,9 @@ impl HevcEncoder {
             frame_count: 0,
             last_keyframe: 0,
             keyframe_interval: TARGET_FPS as u64 * 2, // Keyframe every 2 seconds
+            last_frame_keyframe: false,
             is_initialized: false,
+            settings: EncoderProfile::LowLatency.settings(CodecFamily::X265),
         }
     }
 
@@ -86,17 +90,11 @@ impl HevcEncoder {
         encoder.set_frame_rate(ffmpeg::Rational::new(fps as i32, 1));
         encoder.set_bit_rate(self.bitrate as usize);
         
-        // HEVC-specific settings for low latency
-        encoder.set_option("preset", DEFAULT_PRESET)
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set preset: {}", e)))?;
-        encoder.set_option("tune", DEFAULT_TUNE)
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set tune: {}", e)))?;
-        encoder.set_option("crf", "25") // Slightly higher CRF for better compression
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set CRF: {}", e)))?;
-        
-        // HEVC quality settings
-        encoder.set_option("x265-params", "bframes=0:no-scenecut:keyint=120:min-keyint=120")
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set x265 params: {}", e)))?;
+        // Apply the active profile: preset, tune, GOP, B-frames and rate control
+        for (key, value) in self.initialization_options() {
+            encoder.set_option(key, &value)
+                .map_err(|e| GhostLinkError::Other(format!("Failed to set {}: {}", key, e)))?;
+        }
         
         // Open encoder
         let encoder = encoder.open()
@@ -157,7 +155,12 @@ impl HevcEncoder {
         // Set frame timing
         yuv_frame.set_pts(Some(self.frame_count as i64));
         
-        // Let the encoder decide when to insert keyframes based on GOP settings
+        // Let the encoder decide when to insert keyframes based on GOP settings,
+        // unless a refresh asked for one right away
+        if self.last_keyframe == 0 {
+            yuv_frame.set_kind(ffmpeg::picture::Type::I);
+            self.last_keyframe = self.frame_count;
+        }
         
         // Encode frame
         context.ffmpeg_context.send_frame(&yuv_frame)
@@ -168,6 +171,7 @@ impl HevcEncoder {
         match context.ffmpeg_context.receive_packet(&mut packet) {
             Ok(_) => {
                 let encoded_data = packet.data().unwrap_or(&[]).to_vec();
+                self.last_frame_keyframe = packet.is_key();
                 debug!("HEVC encoded frame {} -> {} bytes", self.frame_count, encoded_data.len());
                 Ok(encoded_data)
             }
@@ -182,6 +186,11 @@ impl HevcEncoder {
         }
     }
 
+    /// FFmpeg options the encoder is opened with under the active profile
+    pub fn initialization_options(&self) -> Vec<(&'static str, String)> {
+        self.settings.ffmpeg_options(self.fps, self.bitrate)
+    }
+
     /// Fallback encoding
     #[cfg(not(feature = "x264-encoder"))]
     fn encode_frame_fallback(&mut self, frame: &Frame) -> Result<Vec<u8>> {
@@ -202,6 +211,8 @@ impl HevcEncoder {
                 .map_err(|e| GhostLinkError::Other(format!("PNG encoding failed: {}", e)))?;
         }
 
+        // PNG frames always stand alone
+        self.last_frame_keyframe = true;
         debug!("PNG encoded frame {} -> {} bytes", self.frame_count, png_data.len());
         Ok(png_data)
     }
@@ -215,7 +226,7 @@ impl VideoEncoder for HevcEncoder {
         self.width = width;
         self.height = height;
         self.fps = fps;
-        self.keyframe_interval = fps as u64 * 2; // Keyframe every 2 seconds
+        self.keyframe_interval = self.settings.gop_length(fps) as u64;
         
         // Try to initialize with hardware acceleration first
         #[cfg(feature = "x264-encoder")]
@@ -281,7 +292,42 @@ impl VideoEncoder for HevcEncoder {
             hardware_accelerated: cfg!(feature = "x264-encoder"),
             supported_formats: vec![PixelFormat::RGBA, PixelFormat::BGRA],
             max_resolution: (3840, 2160), // Support up to 4K
+            profile: Some(self.settings.profile),
+            skipped: Vec::new(),
+        }
+    }
+
+    fn apply_profile(&mut self, settings: &EncoderSettings) {
+        info!("Applying {} profile to HEVC encoder", settings.profile);
+        self.settings = settings.clone();
+        self.keyframe_interval = self.settings.gop_length(self.fps) as u64;
+    }
+
+    fn set_bitrate(&mut self, kbps: u32) {
+        let target_bitrate = kbps.saturating_mul(1000);
+        if target_bitrate == self.bitrate {
+            return;
         }
+        info!("Adjusting HEVC bitrate from {} to {} bps", self.bitrate, target_bitrate);
+        self.bitrate = target_bitrate;
+
+        // libx265 takes its rate control at open time, so reopen the encoder
+        #[cfg(feature = "x264-encoder")]
+        {
+            if self.encoder_context.is_some() {
+                if let Err(e) = self.init_ffmpeg_encoder(self.width, self.height, self.fps) {
+                    warn!("Failed to reopen HEVC encoder at {} bps: {}", self.bitrate, e);
+                }
+            }
+        }
+    }
+
+    fn force_keyframe(&mut self) {
+        self.last_keyframe = 0;
+    }
+
+    fn last_frame_was_keyframe(&self) -> bool {
+        self.last_frame_keyframe
     }
 
     fn is_healthy(&self) -> bool {
@@ -3,15 +3,28 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::{RwLock, Mutex};
-use tokio::time::{interval, Duration};
+use tokio::sync::{broadcast, mpsc, RwLock, Mutex};
+use tokio::time::{Duration, Instant};
 use tracing::{debug, error, info, warn, trace};
 
 use crate::{
     session::SessionType,
     error::Result,
+    recording::SessionRecorder,
+    resources::{self, Lease},
 };
 
+use adaptive::{AdaptiveController, CaptureStats, FrameSample};
+use bandwidth::{BandwidthLimiter, ColorDepth};
+use cursor::{CursorSample, CursorTracker, CursorUpdate};
+use displays::{CaptureTarget, DisplayEvent, DisplaySwitcher};
+use encoder_factory::{EncoderFactory, EncoderKind, EncoderSelection, SkippedEncoder};
+use encoder_profile::{CodecFamily, EncoderProfile, EncoderSettings, EncodingPolicy, H264Profile};
+use frame_diff::{DirtyRect, FrameChange, FrameDiffer};
+use negotiation::{StreamCodec, StreamConfig, StreamPlan, ViewerCapabilities};
+#[cfg(target_os = "linux")]
+use wayland::dmabuf::DmaBufFrame;
+
 
 #[cfg(target_os = "linux")]
 pub mod wayland;
@@ -28,18 +41,74 @@ pub mod wayland_fast;
 
 #[cfg(target_os = "windows")]
 pub mod windows;
+#[cfg(any(target_os = "windows", test))]
+pub mod duplication;
 
 #[cfg(target_os = "macos")]
 pub mod macos;
 
+pub mod adaptive;
+pub mod bandwidth;
+pub mod budget;
+pub mod cursor;
+pub mod damage;
+pub mod display_watch;
+pub mod displays;
 pub mod encoding;
+pub mod frame_diff;
 pub mod h264_encoder;
 pub mod hevc_encoder;
 pub mod nvenc_encoder;
 pub mod encoder_factory;
+pub mod encoder_profile;
 pub mod frame_streaming;
 pub mod frame_protocol;
 pub mod monitor_manager;
+pub mod negotiation;
+pub mod tiles;
+
+/// Frame rate the session capture loop starts at
+const CAPTURE_FPS: u32 = 30;
+
+/// Encoded frames that may wait for the relay before new ones are dropped
+const OUTBOUND_QUEUE_FRAMES: usize = 8;
+
+/// Which capturer to prefer on Linux, from the config's `[capture]` section
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Capture through the desktop portal and PipeWire under Wayland;
+    /// `GHOSTLINK_USE_PORTAL` takes precedence
+    pub use_portal: bool,
+    /// Use the shared-memory capturers over the plain ones;
+    /// `GHOSTLINK_FAST_CAPTURE` takes precedence
+    pub fast_capture: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            use_portal: true,
+            fast_capture: true,
+        }
+    }
+}
+
+/// Capture settings of the loaded config; capturers are also created by
+/// the service helper and the pre-flight, which have no config at hand
+static CAPTURE_CONFIG: std::sync::OnceLock<CaptureConfig> = std::sync::OnceLock::new();
+
+impl CaptureConfig {
+    /// Use these settings for every capturer created from now on; only
+    /// the first call counts
+    pub fn install(self) {
+        let _ = CAPTURE_CONFIG.set(self);
+    }
+
+    fn current() -> Self {
+        CAPTURE_CONFIG.get().cloned().unwrap_or_default()
+    }
+}
 
 /// Cross-platform screen capture abstraction
 pub struct ScreenCapture {
@@ -47,6 +116,26 @@ pub struct ScreenCapture {
     encoder: Arc<RwLock<Option<VideoEncoderEnum>>>,
     is_streaming: Arc<RwLock<bool>>,
     session_type: SessionType,
+    profile: Arc<RwLock<EncoderProfile>>,
+    /// Encoder to use instead of the fallback chain, for debugging
+    forced_encoder: Option<EncoderKind>,
+    /// What the viewer announced it decodes, if it did
+    viewer: Arc<parking_lot::Mutex<Option<ViewerCapabilities>>>,
+    /// Codec and encoder confirmed to the viewer
+    stream_config: Arc<parking_lot::Mutex<Option<StreamConfig>>>,
+    controller: Arc<parking_lot::Mutex<AdaptiveController>>,
+    /// Holds encoded frames to the session's bandwidth cap
+    limiter: Arc<BandwidthLimiter>,
+    differ: Arc<parking_lot::Mutex<FrameDiffer>>,
+    stats: Arc<parking_lot::Mutex<CaptureStats>>,
+    displays: Arc<Mutex<DisplaySwitcher>>,
+    display_events: broadcast::Sender<DisplayEvent>,
+    /// Whether cursor updates are sent beside the frames
+    cursor_streaming: Arc<std::sync::atomic::AtomicBool>,
+    cursor_tracker: Arc<parking_lot::Mutex<CursorTracker>>,
+    cursor_updates: broadcast::Sender<CursorUpdate>,
+    /// Receives a copy of every encoded frame while the session is recorded
+    recorder: Arc<parking_lot::Mutex<Option<Arc<SessionRecorder>>>>,
     capture_task_handle: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
 }
 
@@ -203,6 +292,132 @@ impl ScreenCapturer for ScreenCapturerEnum {
         }
     }
 
+    fn take_damage(&mut self) -> Option<Vec<DirtyRect>> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::WaylandPortal(capturer) => capturer.take_damage(),
+            #[cfg(target_os = "linux")]
+            Self::Wayland(capturer) => capturer.take_damage(),
+            #[cfg(target_os = "linux")]
+            Self::X11(capturer) => capturer.take_damage(),
+            #[cfg(target_os = "linux")]
+            Self::X11Fast(capturer) => capturer.take_damage(),
+            #[cfg(target_os = "linux")]
+            Self::WaylandFast(capturer) => capturer.take_damage(),
+            #[cfg(target_os = "windows")]
+            Self::Windows(capturer) => capturer.take_damage(),
+            #[cfg(target_os = "macos")]
+            Self::MacOS(capturer) => capturer.take_damage(),
+        }
+    }
+
+    fn take_display_change(&mut self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::WaylandPortal(capturer) => capturer.take_display_change(),
+            #[cfg(target_os = "linux")]
+            Self::Wayland(capturer) => capturer.take_display_change(),
+            #[cfg(target_os = "linux")]
+            Self::X11(capturer) => capturer.take_display_change(),
+            #[cfg(target_os = "linux")]
+            Self::X11Fast(capturer) => capturer.take_display_change(),
+            #[cfg(target_os = "linux")]
+            Self::WaylandFast(capturer) => capturer.take_display_change(),
+            #[cfg(target_os = "windows")]
+            Self::Windows(capturer) => capturer.take_display_change(),
+            #[cfg(target_os = "macos")]
+            Self::MacOS(capturer) => capturer.take_display_change(),
+        }
+    }
+
+    async fn reconfigure(&mut self) -> Result<()> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::WaylandPortal(capturer) => capturer.reconfigure().await,
+            #[cfg(target_os = "linux")]
+            Self::Wayland(capturer) => capturer.reconfigure().await,
+            #[cfg(target_os = "linux")]
+            Self::X11(capturer) => capturer.reconfigure().await,
+            #[cfg(target_os = "linux")]
+            Self::X11Fast(capturer) => capturer.reconfigure().await,
+            #[cfg(target_os = "linux")]
+            Self::WaylandFast(capturer) => capturer.reconfigure().await,
+            #[cfg(target_os = "windows")]
+            Self::Windows(capturer) => capturer.reconfigure().await,
+            #[cfg(target_os = "macos")]
+            Self::MacOS(capturer) => capturer.reconfigure().await,
+        }
+    }
+
+    fn buffer_path(&self) -> BufferPath {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::WaylandPortal(capturer) => capturer.buffer_path(),
+            #[cfg(target_os = "linux")]
+            Self::Wayland(capturer) => capturer.buffer_path(),
+            #[cfg(target_os = "linux")]
+            Self::X11(capturer) => capturer.buffer_path(),
+            #[cfg(target_os = "linux")]
+            Self::X11Fast(capturer) => capturer.buffer_path(),
+            #[cfg(target_os = "linux")]
+            Self::WaylandFast(capturer) => capturer.buffer_path(),
+            #[cfg(target_os = "windows")]
+            Self::Windows(capturer) => capturer.buffer_path(),
+            #[cfg(target_os = "macos")]
+            Self::MacOS(capturer) => capturer.buffer_path(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn take_dmabuf(&mut self) -> Option<DmaBufFrame> {
+        match self {
+            Self::WaylandPortal(capturer) => capturer.take_dmabuf(),
+            Self::Wayland(capturer) => capturer.take_dmabuf(),
+            Self::X11(capturer) => capturer.take_dmabuf(),
+            Self::X11Fast(capturer) => capturer.take_dmabuf(),
+            Self::WaylandFast(capturer) => capturer.take_dmabuf(),
+        }
+    }
+
+    fn sample_cursor(&mut self) -> Option<CursorSample> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::WaylandPortal(capturer) => capturer.sample_cursor(),
+            #[cfg(target_os = "linux")]
+            Self::Wayland(capturer) => capturer.sample_cursor(),
+            #[cfg(target_os = "linux")]
+            Self::X11(capturer) => capturer.sample_cursor(),
+            #[cfg(target_os = "linux")]
+            Self::X11Fast(capturer) => capturer.sample_cursor(),
+            #[cfg(target_os = "linux")]
+            Self::WaylandFast(capturer) => capturer.sample_cursor(),
+            // The cursor is system-wide; no capturer handle needed
+            #[cfg(target_os = "windows")]
+            Self::Windows(_) => cursor::system_cursor(),
+            #[cfg(target_os = "macos")]
+            Self::MacOS(capturer) => capturer.sample_cursor(),
+        }
+    }
+
+    fn cursor_in_frames(&self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::WaylandPortal(capturer) => capturer.cursor_in_frames(),
+            #[cfg(target_os = "linux")]
+            Self::Wayland(capturer) => capturer.cursor_in_frames(),
+            #[cfg(target_os = "linux")]
+            Self::X11(capturer) => capturer.cursor_in_frames(),
+            #[cfg(target_os = "linux")]
+            Self::X11Fast(capturer) => capturer.cursor_in_frames(),
+            #[cfg(target_os = "linux")]
+            Self::WaylandFast(capturer) => capturer.cursor_in_frames(),
+            #[cfg(target_os = "windows")]
+            Self::Windows(capturer) => capturer.cursor_in_frames(),
+            #[cfg(target_os = "macos")]
+            Self::MacOS(capturer) => capturer.cursor_in_frames(),
+        }
+    }
+
     async fn cleanup(&mut self) -> Result<()> {
         match self {
             #[cfg(target_os = "linux")]
@@ -279,7 +494,7 @@ impl VideoEncoder for VideoEncoderEnum {
     }
 
     fn get_encoder_info(&self) -> EncoderInfo {
-        match self {
+        let mut info = match self {
             Self::Software(encoder) => encoder.get_encoder_info(),
             Self::H264(encoder) => encoder.get_encoder_info(),
             Self::Hevc(encoder) => encoder.get_encoder_info(),
@@ -293,6 +508,136 @@ impl VideoEncoder for VideoEncoderEnum {
             Self::Qsv(encoder) => encoder.get_encoder_info(),
             #[cfg(feature = "videotoolbox")]
             Self::VideoToolbox(encoder) => encoder.get_encoder_info(),
+        };
+        info.skipped = encoder_factory::skipped_encoders();
+        info
+    }
+
+    fn apply_profile(&mut self, settings: &EncoderSettings) {
+        match self {
+            Self::Software(encoder) => encoder.apply_profile(settings),
+            Self::H264(encoder) => encoder.apply_profile(settings),
+            Self::Hevc(encoder) => encoder.apply_profile(settings),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH264(encoder) => encoder.apply_profile(settings),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH265(encoder) => encoder.apply_profile(settings),
+            #[cfg(feature = "nvenc")]
+            Self::NvencAV1(encoder) => encoder.apply_profile(settings),
+            #[cfg(feature = "qsv")]
+            Self::Qsv(encoder) => encoder.apply_profile(settings),
+            #[cfg(feature = "videotoolbox")]
+            Self::VideoToolbox(encoder) => encoder.apply_profile(settings),
+        }
+    }
+
+    fn set_bitrate(&mut self, kbps: u32) {
+        match self {
+            Self::Software(encoder) => encoder.set_bitrate(kbps),
+            Self::H264(encoder) => encoder.set_bitrate(kbps),
+            Self::Hevc(encoder) => encoder.set_bitrate(kbps),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH264(encoder) => encoder.set_bitrate(kbps),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH265(encoder) => encoder.set_bitrate(kbps),
+            #[cfg(feature = "nvenc")]
+            Self::NvencAV1(encoder) => encoder.set_bitrate(kbps),
+            #[cfg(feature = "qsv")]
+            Self::Qsv(encoder) => encoder.set_bitrate(kbps),
+            #[cfg(feature = "videotoolbox")]
+            Self::VideoToolbox(encoder) => encoder.set_bitrate(kbps),
+        }
+    }
+
+    fn set_dirty_regions(&mut self, regions: &[DirtyRect]) {
+        match self {
+            Self::Software(encoder) => encoder.set_dirty_regions(regions),
+            Self::H264(encoder) => encoder.set_dirty_regions(regions),
+            Self::Hevc(encoder) => encoder.set_dirty_regions(regions),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH264(encoder) => encoder.set_dirty_regions(regions),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH265(encoder) => encoder.set_dirty_regions(regions),
+            #[cfg(feature = "nvenc")]
+            Self::NvencAV1(encoder) => encoder.set_dirty_regions(regions),
+            #[cfg(feature = "qsv")]
+            Self::Qsv(encoder) => encoder.set_dirty_regions(regions),
+            #[cfg(feature = "videotoolbox")]
+            Self::VideoToolbox(encoder) => encoder.set_dirty_regions(regions),
+        }
+    }
+
+    fn force_keyframe(&mut self) {
+        match self {
+            Self::Software(encoder) => encoder.force_keyframe(),
+            Self::H264(encoder) => encoder.force_keyframe(),
+            Self::Hevc(encoder) => encoder.force_keyframe(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH264(encoder) => encoder.force_keyframe(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH265(encoder) => encoder.force_keyframe(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencAV1(encoder) => encoder.force_keyframe(),
+            #[cfg(feature = "qsv")]
+            Self::Qsv(encoder) => encoder.force_keyframe(),
+            #[cfg(feature = "videotoolbox")]
+            Self::VideoToolbox(encoder) => encoder.force_keyframe(),
+        }
+    }
+
+    fn last_frame_was_keyframe(&self) -> bool {
+        match self {
+            Self::Software(encoder) => encoder.last_frame_was_keyframe(),
+            Self::H264(encoder) => encoder.last_frame_was_keyframe(),
+            Self::Hevc(encoder) => encoder.last_frame_was_keyframe(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH264(encoder) => encoder.last_frame_was_keyframe(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH265(encoder) => encoder.last_frame_was_keyframe(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencAV1(encoder) => encoder.last_frame_was_keyframe(),
+            #[cfg(feature = "qsv")]
+            Self::Qsv(encoder) => encoder.last_frame_was_keyframe(),
+            #[cfg(feature = "videotoolbox")]
+            Self::VideoToolbox(encoder) => encoder.last_frame_was_keyframe(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn imports_dmabuf(&self) -> bool {
+        match self {
+            Self::Software(encoder) => encoder.imports_dmabuf(),
+            Self::H264(encoder) => encoder.imports_dmabuf(),
+            Self::Hevc(encoder) => encoder.imports_dmabuf(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH264(encoder) => encoder.imports_dmabuf(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencH265(encoder) => encoder.imports_dmabuf(),
+            #[cfg(feature = "nvenc")]
+            Self::NvencAV1(encoder) => encoder.imports_dmabuf(),
+            #[cfg(feature = "qsv")]
+            Self::Qsv(encoder) => encoder.imports_dmabuf(),
+            #[cfg(feature = "videotoolbox")]
+            Self::VideoToolbox(encoder) => encoder.imports_dmabuf(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn encode_dmabuf(&mut self, buffer: &DmaBufFrame, frame: &Frame) -> Result<Vec<u8>> {
+        match self {
+            Self::Software(encoder) => encoder.encode_dmabuf(buffer, frame).await,
+            Self::H264(encoder) => encoder.encode_dmabuf(buffer, frame).await,
+            Self::Hevc(encoder) => encoder.encode_dmabuf(buffer, frame).await,
+            #[cfg(feature = "nvenc")]
+            Self::NvencH264(encoder) => encoder.encode_dmabuf(buffer, frame).await,
+            #[cfg(feature = "nvenc")]
+            Self::NvencH265(encoder) => encoder.encode_dmabuf(buffer, frame).await,
+            #[cfg(feature = "nvenc")]
+            Self::NvencAV1(encoder) => encoder.encode_dmabuf(buffer, frame).await,
+            #[cfg(feature = "qsv")]
+            Self::Qsv(encoder) => encoder.encode_dmabuf(buffer, frame).await,
+            #[cfg(feature = "videotoolbox")]
+            Self::VideoToolbox(encoder) => encoder.encode_dmabuf(buffer, frame).await,
         }
     }
 
@@ -357,6 +702,49 @@ pub trait ScreenCapturer: Send + Sync {
     /// Check if capturer is healthy
     fn is_healthy(&self) -> bool;
     
+    /// Regions the last captured frame changed in, for capturers that know;
+    /// `None` leaves finding them to the frame differ
+    fn take_damage(&mut self) -> Option<Vec<DirtyRect>> {
+        None
+    }
+    
+    /// How the last frame's pixels reached the capture loop
+    fn buffer_path(&self) -> BufferPath {
+        BufferPath::Copy
+    }
+    
+    /// Whether the display configuration changed since the last call, for
+    /// capturers that are told about changes
+    fn take_display_change(&mut self) -> bool {
+        false
+    }
+    
+    /// Pick up a changed display configuration: re-read the geometry and
+    /// size buffers for it
+    async fn reconfigure(&mut self) -> Result<()> {
+        // Capturers that query the geometry per frame have nothing to redo
+        Ok(())
+    }
+    
+    /// The DMA-BUF behind the last captured frame, for encoders that
+    /// import it
+    #[cfg(target_os = "linux")]
+    fn take_dmabuf(&mut self) -> Option<DmaBufFrame> {
+        None
+    }
+    
+    /// Cursor position, and shape where the platform exposes it, for
+    /// streaming beside the frames
+    fn sample_cursor(&mut self) -> Option<CursorSample> {
+        // Capturers that cannot see the cursor report nothing
+        None
+    }
+    
+    /// Whether captured frames already show the cursor
+    fn cursor_in_frames(&self) -> bool {
+        false
+    }
+    
     /// Cleanup resources
     async fn cleanup(&mut self) -> Result<()>;
 }
@@ -376,6 +764,46 @@ pub trait VideoEncoder: Send + Sync {
     /// Check if encoder is healthy
     fn is_healthy(&self) -> bool;
     
+    /// Apply preset profile settings; hardware and FFmpeg encoders pick them
+    /// up on the next `initialize`
+    fn apply_profile(&mut self, _settings: &EncoderSettings) {
+        // Encoders without tunable settings ignore profiles
+    }
+    
+    /// Target bitrate hint from the adaptive controller, in kbit/s
+    fn set_bitrate(&mut self, _kbps: u32) {
+        // Encoders without rate control ignore the hint
+    }
+    
+    /// Regions that changed since the previous frame, ahead of `encode_frame`
+    fn set_dirty_regions(&mut self, _regions: &[DirtyRect]) {
+        // Encoders that always code the whole frame ignore the hint
+    }
+    
+    /// Make the next encoded frame decodable on its own
+    fn force_keyframe(&mut self) {
+        // Intra-only encoders already produce nothing else
+    }
+    
+    /// Whether the output of the last `encode_frame` decodes on its own
+    fn last_frame_was_keyframe(&self) -> bool {
+        true
+    }
+    
+    /// Whether `encode_dmabuf` reads the DMA-BUF itself, sparing the upload
+    /// from system memory
+    #[cfg(target_os = "linux")]
+    fn imports_dmabuf(&self) -> bool {
+        false
+    }
+    
+    /// Encode a frame whose pixels are still in `buffer`; `frame` holds the
+    /// same pixels mapped into system memory
+    #[cfg(target_os = "linux")]
+    async fn encode_dmabuf(&mut self, _buffer: &DmaBufFrame, frame: &Frame) -> Result<Vec<u8>> {
+        self.encode_frame(frame).await
+    }
+    
     /// Cleanup encoder resources
     async fn cleanup(&mut self) -> Result<()> {
         // Default implementation does nothing
@@ -405,8 +833,23 @@ pub enum PixelFormat {
     NV12,
 }
 
+/// How captured pixels reach the capture loop
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferPath {
+    /// Copied out of the display server for every frame
+    #[default]
+    Copy,
+    /// Shared memory with the X server or PipeWire
+    Shm,
+    /// DMA-BUF from PipeWire, mapped once into system memory
+    DmaBuf,
+    /// DMA-BUF handed straight to an encoder that imports it
+    DmaBufImport,
+}
+
 /// Display/monitor information
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DisplayInfo {
     pub id: u32,
     pub name: String,
@@ -424,38 +867,69 @@ pub struct EncoderInfo {
     pub hardware_accelerated: bool,
     pub supported_formats: Vec<PixelFormat>,
     pub max_resolution: (u32, u32),
+    /// Active preset profile, if the encoder has one applied
+    pub profile: Option<EncoderProfile>,
+    /// Better encoders the fallback chain passed over on this machine
+    pub skipped: Vec<SkippedEncoder>,
 }
 
 impl ScreenCapture {
-    /// Create new screen capture instance
-    pub async fn new(session_type: SessionType) -> Result<Self> {
+    /// Create new screen capture instance for a session type under the
+    /// organization's encoding `policy`: its profile for the session type,
+    /// its adaptive bounds and max keyframe interval, and its forced encoder
+    pub async fn new(session_type: SessionType, policy: &EncodingPolicy) -> Result<Self> {
+        let screen_capture = Self::open(session_type, policy).await?;
+        screen_capture.start_encoder(None).await?;
+        Ok(screen_capture)
+    }
+
+    /// Create the capture with its capturer initialized but no encoder
+    /// yet, so the session pre-flight can check each step on its own
+    pub async fn open(session_type: SessionType, policy: &EncodingPolicy) -> Result<Self> {
         let capturer = Self::create_platform_capturer().await?;
+        let profile = policy.profile_for(session_type);
+        // Frame rate, color and tiles are the same for every codec family
+        let settings = profile.settings(CodecFamily::Software);
+        let mut controller = AdaptiveController::new(policy.adaptive.clone(), CAPTURE_FPS);
+        controller.apply_profile(&settings);
+        let displays = DisplaySwitcher::new(&capturer);
+        let (display_events, _) = broadcast::channel(16);
+        let (cursor_updates, _) = broadcast::channel(64);
         
-        let mut screen_capture = Self {
+        let screen_capture = Self {
             capturer: Arc::new(Mutex::new(capturer)),
             encoder: Arc::new(RwLock::new(None)),
             is_streaming: Arc::new(RwLock::new(false)),
             session_type,
+            profile: Arc::new(RwLock::new(profile)),
+            forced_encoder: policy.forced_encoder(),
+            viewer: Arc::new(parking_lot::Mutex::new(None)),
+            stream_config: Arc::new(parking_lot::Mutex::new(None)),
+            controller: Arc::new(parking_lot::Mutex::new(controller)),
+            limiter: Arc::new(BandwidthLimiter::new()),
+            differ: Arc::new(parking_lot::Mutex::new(FrameDiffer::new(settings.tile_size, policy.max_keyframe_interval()))),
+            stats: Arc::new(parking_lot::Mutex::new(CaptureStats::default())),
+            displays: Arc::new(Mutex::new(displays)),
+            display_events,
+            cursor_streaming: Arc::new(std::sync::atomic::AtomicBool::new(true)),
+            cursor_tracker: Arc::new(parking_lot::Mutex::new(CursorTracker::new())),
+            cursor_updates,
+            recorder: Arc::new(parking_lot::Mutex::new(None)),
             capture_task_handle: Arc::new(Mutex::new(None)),
         };
         
-        screen_capture.initialize().await?;
+        screen_capture.set_bandwidth_ceiling(policy.max_bandwidth_kbps);
+        screen_capture.initialize_capturer().await?;
         
         Ok(screen_capture)
     }
 
     /// Create platform-specific capturer
-    async fn create_platform_capturer() -> Result<ScreenCapturerEnum> {
+    pub(crate) async fn create_platform_capturer() -> Result<ScreenCapturerEnum> {
         #[cfg(target_os = "linux")]
         {
-            // Check for capture method preference
-            let use_portal = std::env::var("GHOSTLINK_USE_PORTAL")
-                .map(|v| v == "1" || v.to_lowercase() == "true")
-                .unwrap_or(true); // Default to Portal-based capture (Wayland-native)
-
-            let use_fast_capture = std::env::var("GHOSTLINK_FAST_CAPTURE")
-                .map(|v| v == "1" || v.to_lowercase() == "true")
-                .unwrap_or(true);
+            // Portal capture is the Wayland-native default
+            let CaptureConfig { use_portal, fast_capture: use_fast_capture } = CaptureConfig::current();
 
             // Try Wayland first, fall back to X11
             if std::env::var("WAYLAND_DISPLAY").is_ok() {
@@ -507,30 +981,117 @@ impl ScreenCapture {
         }
     }
 
-    /// Initialize screen capture
-    async fn initialize(&mut self) -> Result<()> {
+    /// Initialize the platform capturer
+    async fn initialize_capturer(&self) -> Result<()> {
         info!("Initializing screen capture");
-        
-        {
-            let mut capturer_guard = self.capturer.lock().await;
-            capturer_guard.initialize().await?;
-        }
-        
-        // Initialize encoder
+        self.capturer.lock().await.initialize().await
+    }
+
+    /// Grab one frame, to prove the capturer delivers before a session
+    /// reports itself ready
+    pub async fn test_frame(&self) -> Result<Frame> {
+        self.capturer.lock().await.capture_frame().await
+    }
+
+    /// Pick and initialize the encoder for the capturer's resolution. A
+    /// `probe` frame is encoded once to prove the encoder works; the
+    /// stream still starts on a keyframe.
+    pub async fn start_encoder(&self, probe: Option<&Frame>) -> Result<()> {
         let (width, height) = {
             let capturer_guard = self.capturer.lock().await;
-            capturer_guard.get_resolution()
+            // Display ids are only known once the capturer is up
+            let mut displays = DisplaySwitcher::new(&*capturer_guard);
+            let resolution = capturer_guard.get_resolution();
+            displays.set_encoded_size(resolution.0, resolution.1);
+            *self.displays.lock().await = displays;
+            resolution
         };
-        let mut encoder = encoding::create_best_encoder().await?;
-        encoder.initialize(width, height, 30).await?;
+        let profile = *self.profile.read().await;
+        let EncoderSelection { kind, mut encoder, .. } = EncoderFactory::create_probed(self.forced_encoder).await?;
+        encoder.set_profile(profile);
+        encoder.initialize(width, height, CAPTURE_FPS).await?;
+        if let Some(frame) = probe {
+            let encoded = encoder.encode_frame(frame).await?;
+            debug!("Test frame encoded to {} bytes", encoded.len());
+            self.request_keyframe();
+        }
+        
+        let info = encoder.get_encoder_info();
+        {
+            let mut stats = self.stats.lock();
+            stats.encoder = info.name;
+            stats.skipped_encoders = info.skipped;
+        }
+        *self.stream_config.lock() = Some(StreamConfig::new(kind, None, width, height));
         
         let mut encoder_guard = self.encoder.write().await;
         *encoder_guard = Some(encoder);
         
-        info!("Screen capture initialized: {}x{}", width, height);
+        info!("Screen capture initialized: {}x{} ({} profile)", width, height, profile);
         Ok(())
     }
 
+    /// Switch to the best encoder `viewer` can decode, returning the config
+    /// to confirm to it before the first frame
+    pub async fn negotiate(&self, viewer: ViewerCapabilities) -> Result<StreamConfig> {
+        let (width, height) = self.get_resolution().await;
+        let mut plan = StreamPlan::negotiate(&viewer, width, height)?;
+        if let Some(forced) = self.forced_encoder {
+            if plan.candidates.contains(&forced) {
+                plan.candidates = vec![forced];
+            } else {
+                warn!("Forced {} encoder is ignored: the viewer cannot decode {}", forced, forced.codec());
+            }
+        }
+        
+        *self.viewer.lock() = Some(viewer);
+        self.switch_encoder(&plan, width, height).await
+    }
+
+    /// Step down to a simpler codec after the viewer failed to decode the
+    /// stream; the session carries on with the returned config
+    pub async fn downgrade(&self) -> Result<StreamConfig> {
+        let current = self.stream_config().ok_or(crate::error::CaptureError::NotInitialized)?;
+        // Viewers that announced nothing are assumed to decode what they got
+        let viewer = self.viewer.lock().clone().unwrap_or_else(|| ViewerCapabilities {
+            codecs: vec![current.codec],
+            ..ViewerCapabilities::default()
+        });
+        
+        let plan = StreamPlan::downgrade(&current, &viewer)?;
+        let (width, height) = self.get_resolution().await;
+        let config = self.switch_encoder(&plan, width, height).await?;
+        info!("Stream downgraded from {} to {}", current.codec, config.codec);
+        Ok(config)
+    }
+
+    /// Codec and encoder the stream currently uses
+    pub fn stream_config(&self) -> Option<StreamConfig> {
+        self.stream_config.lock().clone()
+    }
+
+    /// Replace the encoder with the first of `plan` that works, starting the
+    /// new stream on a keyframe
+    async fn switch_encoder(&self, plan: &StreamPlan, width: u32, height: u32) -> Result<StreamConfig> {
+        let profile = *self.profile.read().await;
+        let EncoderSelection { kind, mut encoder, .. } = EncoderFactory::create_from(&plan.candidates).await?;
+        let config = plan.config(kind, width, height);
+        encoder.set_stream_profile(profile, config.h264_profile);
+        encoder.set_tile_mode(config.codec == StreamCodec::Tiles);
+        encoder.initialize(width, height, CAPTURE_FPS).await?;
+        self.stats.lock().encoder = encoder.get_encoder_info().name;
+        
+        let old = self.encoder.write().await.replace(encoder);
+        if let Some(mut old) = old {
+            let _ = old.cleanup().await;
+        }
+        *self.stream_config.lock() = Some(config.clone());
+        self.request_refresh();
+        
+        info!("Streaming {} with {} encoder", config.codec, config.encoder);
+        Ok(config)
+    }
+
     /// Start streaming screen capture
     pub async fn start_streaming(&self) -> Result<()> {
         let mut streaming_guard = self.is_streaming.write().await;
@@ -554,33 +1115,172 @@ impl ScreenCapture {
         let capturer = Arc::clone(&self.capturer);
         let encoder = Arc::clone(&self.encoder);
         let is_streaming = Arc::clone(&self.is_streaming);
+        let controller = Arc::clone(&self.controller);
+        let differ = Arc::clone(&self.differ);
+        let stats = Arc::clone(&self.stats);
+        let displays = Arc::clone(&self.displays);
+        let display_events = self.display_events.clone();
+        let cursor_streaming = Arc::clone(&self.cursor_streaming);
+        let cursor_tracker = Arc::clone(&self.cursor_tracker);
+        let cursor_updates = self.cursor_updates.clone();
+        let recorder = Arc::clone(&self.recorder);
+        let stream_config = Arc::clone(&self.stream_config);
+        
+        // Bumped whenever the frame size changes, so frames still queued
+        // at the old size are dropped instead of sent
+        let geometry = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        
+        // Encoded frames wait here for the relay; its depth tells the
+        // controller whether the network keeps up. Each holds its bytes in
+        // the memory pool until it is sent or dropped.
+        let (frame_tx, mut frame_rx) = mpsc::channel::<(u64, Vec<u8>, Lease<'static>)>(OUTBOUND_QUEUE_FRAMES);
+        let sender_stats = Arc::clone(&self.stats);
+        let sender_geometry = Arc::clone(&geometry);
+        let sender_differ = Arc::clone(&self.differ);
+        let limiter = Arc::clone(&self.limiter);
+        tokio::spawn(async move {
+            while let Some((generation, encoded_data, _lease)) = frame_rx.recv().await {
+                if generation != sender_geometry.load(std::sync::atomic::Ordering::SeqCst) {
+                    trace!("Dropping frame encoded at the previous display size");
+                    continue;
+                }
+                // Queued frames go first when buffers near the memory ceiling
+                if resources::memory_pool().under_pressure() {
+                    trace!("Memory ceiling near, dropping queued frame");
+                    resources::memory_pool().note_shed(1);
+                    sender_stats.lock().frames_shed += 1;
+                    sender_differ.lock().request_refresh();
+                    continue;
+                }
+                // Waiting here backs the queue up, which slows the capture loop
+                limiter.admit(encoded_data.len()).await;
+                match Self::send_frame_to_relay(encoded_data).await {
+                    Ok(()) => sender_stats.lock().frames_sent += 1,
+                    Err(e) => error!("Failed to send frame to relay: {}", e),
+                }
+            }
+        });
         
         // Spawn capture loop task
         let handle = tokio::spawn(async move {
             info!("Capture loop started");
-            let mut capture_interval = interval(Duration::from_millis(33)); // ~30 FPS
+            let mut next_tick = Instant::now();
             
             while *is_streaming.read().await {
-                capture_interval.tick().await;
+                tokio::time::sleep_until(next_tick).await;
+                let started = Instant::now();
                 
-                // Capture frame
+                // Capture frame from the selected display, or all of them;
+                // none while a display change settles
                 let frame_result = {
+                    let mut displays_guard = displays.lock().await;
                     let mut capturer_guard = capturer.lock().await;
-                    capturer_guard.capture_frame().await
+                    let grab_started = Instant::now();
+                    let result = displays_guard.capture(&mut *capturer_guard).await;
+                    {
+                        let mut stats = stats.lock();
+                        stats.grab_time_ms = grab_started.elapsed().as_secs_f64() * 1000.0;
+                        stats.buffer_path = capturer_guard.buffer_path();
+                    }
+                    result.map(|(frame, events)| {
+                        let damage = displays_guard.take_damage(&mut *capturer_guard);
+                        (frame, events, damage)
+                    })
                 };
                 
+                // The cursor has its own channel, so it moves even while
+                // the frames stand still
+                if cursor_streaming.load(std::sync::atomic::Ordering::Relaxed) {
+                    let sample = capturer.lock().await.sample_cursor();
+                    if let Some(update) = sample.and_then(|sample| cursor_tracker.lock().update(sample)) {
+                        let _ = cursor_updates.send(update);
+                    }
+                }
+                
                 match frame_result {
-                    Ok(frame) => {
+                    Ok((frame, events, damage)) => {
+                        for event in events {
+                            if let DisplayEvent::Switched { width, height, .. } = event {
+                                geometry.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                                if let Some(config) = stream_config.lock().as_mut() {
+                                    config.width = width;
+                                    config.height = height;
+                                }
+                            }
+                            let _ = display_events.send(event);
+                        }
+                        let Some(mut frame) = frame else {
+                            next_tick = (next_tick + controller.lock().interval()).max(Instant::now());
+                            continue;
+                        };
                         debug!("Captured frame: {}x{}", frame.width, frame.height);
+                        let Some(_frame_lease) = resources::memory_pool().reserve(frame.data.len()) else {
+                            trace!("Memory ceiling reached, skipping frame");
+                            resources::memory_pool().note_shed(1);
+                            stats.lock().frames_shed += 1;
+                            next_tick = (next_tick + controller.lock().interval()).max(Instant::now());
+                            continue;
+                        };
                         
-                        // Encode frame if encoder is available
-                        if let Some(encoder) = encoder.write().await.as_mut() {
-                            match encoder.encode_frame(&frame).await {
+                        // Reduced before diffing, so color noise does not count as change
+                        let color_depth = controller.lock().color_depth();
+                        color_depth.apply(&mut frame);
+                        
+                        // Capturers that track damage spare the differ most of the frame
+                        let change = match &damage {
+                            Some(damage) => differ.lock().diff_damaged(&frame, damage),
+                            None => differ.lock().diff(&frame),
+                        };
+                        
+                        // Encode frame if it changed and an encoder is available
+                        if change == FrameChange::Unchanged {
+                            stats.lock().frames_unchanged += 1;
+                        } else if let Some(encoder) = encoder.write().await.as_mut() {
+                            // A display switch or hot-plug changed the frame size
+                            let resized = displays.lock().await
+                                .prepare_encoder(encoder, &frame, CAPTURE_FPS).await;
+                            if let Err(e) = resized {
+                                error!("Failed to re-initialize encoder: {}", e);
+                            }
+                            
+                            let bitrate_hint = controller.lock().take_bitrate_hint();
+                            if let Some(kbps) = bitrate_hint {
+                                encoder.set_bitrate(kbps);
+                            }
+                            
+                            match &change {
+                                FrameChange::Full => {
+                                    encoder.force_keyframe();
+                                    stats.lock().full_refreshes += 1;
+                                }
+                                FrameChange::Partial(regions) => encoder.set_dirty_regions(regions),
+                                FrameChange::Unchanged => {}
+                            }
+                            
+                            match Self::encode_captured(encoder, &frame, color_depth, &displays, &capturer, &stats).await {
+                                Ok(encoded_data) if encoded_data.is_empty() => {}
                                 Ok(encoded_data) => {
                                     debug!("Frame encoded: {} bytes", encoded_data.len());
-                                    // Send encoded data via websocket/relay
-                                    if let Err(e) = Self::send_frame_to_relay(encoded_data).await {
-                                        error!("Failed to send frame to relay: {}", e);
+                                    if let Some(recorder) = recorder.lock().as_ref() {
+                                        let keyframe = encoder.last_frame_was_keyframe();
+                                        recorder.record_frame(frame.width, frame.height, keyframe, &encoded_data);
+                                    }
+                                    let generation = geometry.load(std::sync::atomic::Ordering::SeqCst);
+                                    match resources::memory_pool().reserve(encoded_data.len()) {
+                                        Some(lease) => {
+                                            if frame_tx.try_send((generation, encoded_data, lease)).is_err() {
+                                                trace!("Outbound queue full, dropping frame");
+                                                stats.lock().frames_dropped += 1;
+                                                // The viewer missed these changes
+                                                differ.lock().request_refresh();
+                                            }
+                                        }
+                                        None => {
+                                            trace!("Memory ceiling reached, dropping frame");
+                                            resources::memory_pool().note_shed(1);
+                                            stats.lock().frames_shed += 1;
+                                            differ.lock().request_refresh();
+                                        }
                                     }
                                 },
                                 Err(e) => {
@@ -594,6 +1294,36 @@ impl ScreenCapture {
                         tokio::time::sleep(Duration::from_millis(100)).await;
                     }
                 }
+                
+                let busy = started.elapsed();
+                let interval = {
+                    let mut controller = controller.lock();
+                    controller.record(FrameSample {
+                        busy,
+                        queue_depth: frame_tx.max_capacity() - frame_tx.capacity(),
+                    });
+                    controller.fill_stats(&mut stats.lock());
+                    controller.interval()
+                };
+                
+                // The CPU budget may hold the next frame back further
+                let now = Instant::now();
+                let budget_delay = {
+                    let mut budget = budget::shared().lock();
+                    budget.record(busy, now.into_std());
+                    let delay = budget.delay(now.into_std());
+                    budget.fill_stats(&mut stats.lock(), now.into_std());
+                    delay
+                };
+                {
+                    let pool = resources::memory_pool();
+                    let mut stats = stats.lock();
+                    stats.memory_used = pool.used();
+                    stats.memory_limit = pool.limit();
+                }
+                
+                // Keep the cadence, but never burst to catch up on missed ticks
+                next_tick = (next_tick + interval).max(now + budget_delay);
             }
             
             info!("Capture loop stopped");
@@ -631,11 +1361,58 @@ impl ScreenCapture {
         Ok(capturer_guard.get_display_info())
     }
 
-    /// Set capture display (for multi-monitor)
-    pub async fn set_display(&mut self, display_id: u32) -> Result<()> {
+    /// Set capture display (for multi-monitor); the running stream switches
+    /// with its next frame
+    pub async fn set_display(&self, display_id: u32) -> Result<()> {
         info!("Setting capture display to: {}", display_id);
-        let mut capturer_guard = self.capturer.lock().await;
-        capturer_guard.select_display(display_id)
+        self.set_capture_target(CaptureTarget::Display { display_id }).await
+    }
+
+    /// Capture all displays composited into one virtual desktop
+    pub async fn capture_all_displays(&self) -> Result<()> {
+        info!("Capturing all displays as one virtual desktop");
+        self.set_capture_target(CaptureTarget::AllDisplays).await
+    }
+
+    async fn set_capture_target(&self, target: CaptureTarget) -> Result<()> {
+        let event = {
+            let mut displays_guard = self.displays.lock().await;
+            let mut capturer_guard = self.capturer.lock().await;
+            displays_guard.select(&mut *capturer_guard, target)?
+        };
+        let _ = self.display_events.send(event);
+        Ok(())
+    }
+
+    /// What the stream currently captures
+    pub async fn capture_target(&self) -> CaptureTarget {
+        self.displays.lock().await.target()
+    }
+
+    /// Display switches and hot-plug changes, for relaying to the viewer
+    pub fn subscribe_display_events(&self) -> broadcast::Receiver<DisplayEvent> {
+        self.display_events.subscribe()
+    }
+
+    /// Cursor updates, for relaying to the viewer
+    pub fn subscribe_cursor_updates(&self) -> broadcast::Receiver<CursorUpdate> {
+        // A new subscriber needs the current shape
+        self.cursor_tracker.lock().reset();
+        self.cursor_updates.subscribe()
+    }
+
+    /// Turn cursor updates on or off; viewers that draw no cursor overlay
+    /// can save the traffic
+    pub fn set_cursor_streaming(&self, enabled: bool) {
+        let was_enabled = self.cursor_streaming.swap(enabled, std::sync::atomic::Ordering::Relaxed);
+        if enabled && !was_enabled {
+            self.cursor_tracker.lock().reset();
+        }
+    }
+
+    /// Whether captured frames already show the cursor
+    pub async fn cursor_in_frames(&self) -> bool {
+        self.capturer.lock().await.cursor_in_frames()
     }
 
     /// Get current resolution
@@ -650,12 +1427,183 @@ impl ScreenCapture {
         capturer_guard.is_healthy()
     }
 
+    /// Check if the encoder is up; a capture without one is not
+    pub async fn encoder_healthy(&self) -> bool {
+        self.encoder.read().await.as_ref().is_some_and(|encoder| encoder.is_healthy())
+    }
+
+    /// Replace a capturer that lost its display connection or portal
+    /// session with a fresh one from the platform factory. The capture loop
+    /// waits on the capturer lock meanwhile and carries on with the same
+    /// target.
+    pub async fn restart_capturer(&self) -> Result<()> {
+        let mut fresh = Self::create_platform_capturer().await?;
+        if let Err(e) = fresh.initialize().await {
+            let _ = fresh.cleanup().await;
+            return Err(e);
+        }
+        
+        let events = {
+            let mut displays_guard = self.displays.lock().await;
+            let mut capturer_guard = self.capturer.lock().await;
+            let mut old = std::mem::replace(&mut *capturer_guard, fresh);
+            if let Err(e) = old.cleanup().await {
+                warn!("Failed to clean up the replaced capturer: {}", e);
+            }
+            displays_guard.reattach(&mut *capturer_guard)?
+        };
+        for event in events {
+            let _ = self.display_events.send(event);
+        }
+        self.request_refresh();
+        
+        info!("Screen capturer restarted");
+        Ok(())
+    }
+
+    /// Release the encoder's resources and initialize it again at the
+    /// stream's size and profile, starting over on a keyframe
+    pub async fn restart_encoder(&self) -> Result<()> {
+        let (width, height) = match self.stream_config() {
+            Some(config) => (config.width, config.height),
+            None => self.get_resolution().await,
+        };
+        
+        let mut encoder_guard = self.encoder.write().await;
+        let encoder = encoder_guard.as_mut().ok_or(crate::error::CaptureError::NotInitialized)?;
+        if let Err(e) = encoder.cleanup().await {
+            warn!("Failed to clean up the encoder before restarting it: {}", e);
+        }
+        encoder.initialize(width, height, CAPTURE_FPS).await?;
+        self.displays.lock().await.set_encoded_size(width, height);
+        drop(encoder_guard);
+        self.request_refresh();
+        
+        info!("Video encoder restarted at {}x{}", width, height);
+        Ok(())
+    }
+
+    /// Active encoder profile
+    pub async fn encoder_profile(&self) -> EncoderProfile {
+        *self.profile.read().await
+    }
+
+    /// Switch the encoder profile mid-session.
+    ///
+    /// The encoder is re-initialized at the current resolution, so the next
+    /// frame starts a new GOP with the new settings.
+    pub async fn set_encoder_profile(&self, profile: EncoderProfile) -> Result<()> {
+        let (width, height) = self.get_resolution().await;
+        
+        let h264_profile = self.stream_config().and_then(|config| config.h264_profile);
+        let mut encoder_guard = self.encoder.write().await;
+        if let Some(encoder) = encoder_guard.as_mut() {
+            encoder.set_stream_profile(profile, h264_profile);
+            encoder.initialize(width, height, CAPTURE_FPS).await?;
+        }
+        *self.profile.write().await = profile;
+        let settings = profile.settings(CodecFamily::Software);
+        self.controller.lock().apply_profile(&settings);
+        self.differ.lock().set_tile_size(settings.tile_size);
+        
+        info!("Encoder profile switched to {}", profile);
+        Ok(())
+    }
+
+    /// Send the next frame in full, e.g. because a viewer joined
+    pub fn request_refresh(&self) {
+        self.differ.lock().request_refresh();
+    }
+
+    /// Encode the next frame as a keyframe, for a viewer that joined
+    /// mid-stream or lost its reference frame
+    pub fn request_keyframe(&self) {
+        debug!("Keyframe requested");
+        self.request_refresh();
+    }
+
+    /// Tee encoded frames into `recorder`, or stop teeing with `None`
+    pub fn set_recorder(&self, recorder: Option<Arc<SessionRecorder>>) {
+        let starting = recorder.is_some();
+        *self.recorder.lock() = recorder;
+        if starting {
+            // Recordings must open on a keyframe to be playable
+            self.request_refresh();
+        }
+    }
+
+    /// Set the technician's quality preference (0-100), which caps how far
+    /// frame rate and bitrate may ramp up
+    pub fn set_quality(&self, quality: u8) {
+        self.controller.lock().set_quality(quality);
+        info!("Capture quality set to {}", quality.min(100));
+    }
+
+    /// Cap the frame rate the stream may ramp up to
+    pub fn set_max_fps(&self, max_fps: u32) {
+        self.controller.lock().set_max_fps(max_fps);
+        info!("Capture frame rate capped at {}", max_fps.max(1));
+    }
+
+    /// Cap the session's bandwidth, or lift the cap with `None`; a policy
+    /// ceiling still applies. Returns the cap now in force.
+    pub fn set_bandwidth_cap(&self, kbps: Option<u32>) -> Option<u32> {
+        let cap = self.limiter.set_cap(kbps);
+        self.controller.lock().set_bandwidth_cap(cap);
+        info!("Bandwidth cap set to {:?} kbit/s", cap);
+        cap
+    }
+
+    /// Set the organization's bandwidth ceiling no session cap may exceed
+    pub fn set_bandwidth_ceiling(&self, kbps: Option<u32>) -> Option<u32> {
+        let cap = self.limiter.set_ceiling(kbps);
+        self.controller.lock().set_bandwidth_cap(cap);
+        cap
+    }
+
+    /// Feed the link's round-trip time and loss to the adaptive controller
+    pub fn record_link_quality(&self, rtt_ms: f64, loss_percent: f64) {
+        self.controller.lock().record_link(rtt_ms, loss_percent);
+    }
+
+    /// Current frame rate, bitrate and queue depth of the capture pipeline
+    pub fn stats(&self) -> CaptureStats {
+        let mut stats = self.stats.lock().clone();
+        self.controller.lock().fill_stats(&mut stats);
+        stats.throughput_kbps = self.limiter.throughput_kbps();
+        stats
+    }
+
     /// Get encoder information
     pub async fn get_encoder_info(&self) -> Option<EncoderInfo> {
         let encoder_guard = self.encoder.read().await;
         encoder_guard.as_ref().map(|e| e.get_encoder_info())
     }
 
+    /// Encode `frame`, straight from its DMA-BUF when the encoder imports
+    /// those and the frame's colors were left alone
+    async fn encode_captured(
+        encoder: &mut VideoEncoderEnum,
+        frame: &Frame,
+        color_depth: ColorDepth,
+        displays: &Mutex<DisplaySwitcher>,
+        capturer: &Mutex<ScreenCapturerEnum>,
+        stats: &parking_lot::Mutex<CaptureStats>,
+    ) -> Result<Vec<u8>> {
+        #[cfg(target_os = "linux")]
+        if encoder.imports_dmabuf() && color_depth == ColorDepth::Full {
+            let buffer = displays.lock().await.take_dmabuf(&mut *capturer.lock().await);
+            if let Some(buffer) = buffer {
+                stats.lock().buffer_path = BufferPath::DmaBufImport;
+                return encoder.encode_dmabuf(&buffer, frame).await;
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (color_depth, displays, capturer, stats);
+        
+        encoder.encode_frame(frame).await
+    }
+    
     /// Send frame data to relay server
     async fn send_frame_to_relay(frame_data: Vec<u8>) -> Result<()> {
         // TODO: Integrate with actual WebSocket relay client
@@ -835,6 +1783,40 @@ impl ScreenCapturerEnum {
 }
 
 impl VideoEncoderEnum {
+    /// Encoder family, used to look up profile settings
+    pub fn codec_family(&self) -> CodecFamily {
+        match self {
+            VideoEncoderEnum::Software(_) => CodecFamily::Software,
+            VideoEncoderEnum::H264(_) => CodecFamily::X264,
+            VideoEncoderEnum::Hevc(_) => CodecFamily::X265,
+            #[cfg(feature = "nvenc")]
+            VideoEncoderEnum::NvencH264(_) | VideoEncoderEnum::NvencH265(_) | VideoEncoderEnum::NvencAV1(_) => CodecFamily::Nvenc,
+            #[cfg(feature = "qsv")]
+            VideoEncoderEnum::Qsv(_) => CodecFamily::X264,
+            #[cfg(feature = "videotoolbox")]
+            VideoEncoderEnum::VideoToolbox(_) => CodecFamily::X264,
+        }
+    }
+
+    /// Apply a preset profile using this encoder's family settings
+    pub fn set_profile(&mut self, profile: EncoderProfile) {
+        self.set_stream_profile(profile, None);
+    }
+
+    /// Apply a preset profile, holding H.264 output to `h264_profile` for a
+    /// viewer that decodes no more than that
+    pub fn set_stream_profile(&mut self, profile: EncoderProfile, h264_profile: Option<H264Profile>) {
+        let settings = profile.settings(self.codec_family()).with_h264_profile(h264_profile);
+        VideoEncoder::apply_profile(self, &settings);
+    }
+
+    /// Send tile frames of the changed regions, if the encoder can
+    pub fn set_tile_mode(&mut self, enabled: bool) {
+        if let VideoEncoderEnum::Software(encoder) = self {
+            encoder.set_tile_mode(enabled);
+        }
+    }
+
     /// Initialize encoder with settings
     pub async fn initialize(&mut self, width: u32, height: u32, fps: u32) -> Result<()> {
         match self {
@@ -875,7 +1857,7 @@ impl VideoEncoderEnum {
     
     /// Get encoder info
     pub fn get_encoder_info(&self) -> EncoderInfo {
-        match self {
+        let mut info = match self {
             VideoEncoderEnum::Software(encoder) => encoder.get_encoder_info(),
             VideoEncoderEnum::H264(encoder) => encoder.get_encoder_info(),
             VideoEncoderEnum::Hevc(encoder) => encoder.get_encoder_info(),
@@ -889,7 +1871,9 @@ impl VideoEncoderEnum {
             VideoEncoderEnum::Qsv(encoder) => encoder.get_encoder_info(),
             #[cfg(feature = "videotoolbox")]
             VideoEncoderEnum::VideoToolbox(encoder) => encoder.get_encoder_info(),
-        }
+        };
+        info.skipped = encoder_factory::skipped_encoders();
+        info
     }
     
     /// Check if encoder is healthy
@@ -4,6 +4,7 @@ use crate::{
     error::{GhostLinkError, Result},
     capture::{
         ScreenCapturer,
+        displays::CaptureTarget,
         x11_fast::X11FastCapturer,
         wayland_fast::WaylandFastCapturer,
         frame_protocol::{FrameMessage, VideoCodec, QualityLevel},
@@ -109,6 +110,14 @@ pub enum MonitorChangeEvent {
     MonitorChanged(MonitorInfo),
     SelectionChanged(MonitorSelection),
     ConfigurationChanged,
+    /// The session now captures `target` at this size; `fallback` is set when
+    /// the selected display disappeared and the primary took over
+    CaptureSwitched {
+        target: CaptureTarget,
+        width: u32,
+        height: u32,
+        fallback: bool,
+    },
 }
 
 /// Enum wrapper for different multi-monitor capturer implementations
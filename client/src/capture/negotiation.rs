@@ -0,0 +1,330 @@
+//! Codec negotiation between the agent and a session's viewer
+//!
+//! The viewer announces what it decodes when it asks for a session, and the
+//! server hands that to the agent with the session request. The agent walks
+//! its fallback chain restricted to those codecs and confirms the winner in a
+//! `StreamConfig` before the first frame. A viewer that later fails to decode
+//! the stream asks for a downgrade, which steps down to H.264 baseline and
+//! then to JPEG frames, which any viewer can show.
+//!
+//! Viewers that composite tile frames say so, and get those from the
+//! software encoder in place of whole JPEG pictures; a failure there steps
+//! down to the whole pictures.
+
+use serde::{Deserialize, Serialize};
+
+use crate::capture::encoder_factory::EncoderKind;
+use crate::capture::encoder_profile::H264Profile;
+use crate::capture::frame_protocol::PROTOCOL_VERSION;
+use crate::error::{GhostLinkError, Result};
+
+/// Codec of a screen stream, as the viewer names it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamCodec {
+    Av1,
+    H265,
+    H264,
+    Jpeg,
+    Png,
+    /// Changed regions of the screen, see [`crate::capture::tiles`]
+    Tiles,
+}
+
+impl StreamCodec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamCodec::Av1 => "av1",
+            StreamCodec::H265 => "h265",
+            StreamCodec::H264 => "h264",
+            StreamCodec::Jpeg => "jpeg",
+            StreamCodec::Png => "png",
+            StreamCodec::Tiles => "tiles",
+        }
+    }
+
+    /// Whether every frame is a still image, decodable at any size
+    pub fn is_image(&self) -> bool {
+        matches!(self, StreamCodec::Jpeg | StreamCodec::Png)
+    }
+}
+
+impl std::fmt::Display for StreamCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What a viewer can decode
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ViewerCapabilities {
+    pub codecs: Vec<StreamCodec>,
+    /// H.264 profiles the viewer decodes; empty means all of them
+    pub h264_profiles: Vec<H264Profile>,
+    /// Largest video frame the viewer's decoder takes; images are not limited
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Whether the viewer paints tile frames over its current picture
+    pub tiles: bool,
+}
+
+impl ViewerCapabilities {
+    /// Whether the viewer decodes `codec` at `width`x`height`
+    pub fn decodes(&self, codec: StreamCodec, width: u32, height: u32) -> bool {
+        let fits = self.max_width.is_none_or(|max| width <= max) && self.max_height.is_none_or(|max| height <= max);
+        self.codecs.contains(&codec) && (fits || codec.is_image())
+    }
+
+    /// Highest H.264 profile the viewer decodes, or `None` if it takes any
+    pub fn h264_profile(&self) -> Option<H264Profile> {
+        if self.h264_profiles.is_empty() {
+            return None;
+        }
+        self.h264_profiles.iter().copied().max().filter(|&profile| profile != H264Profile::High)
+    }
+}
+
+/// Codec and encoder the agent streams with, confirmed to the viewer before
+/// the first frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamConfig {
+    pub encoder: EncoderKind,
+    pub codec: StreamCodec,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub h264_profile: Option<H264Profile>,
+    pub width: u32,
+    pub height: u32,
+    /// Frame header version of the frames that follow
+    pub protocol_version: u16,
+}
+
+impl StreamConfig {
+    pub fn new(encoder: EncoderKind, h264_profile: Option<H264Profile>, width: u32, height: u32) -> Self {
+        let codec = encoder.codec();
+        Self {
+            encoder,
+            codec,
+            h264_profile: h264_profile.filter(|_| codec == StreamCodec::H264),
+            width,
+            height,
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Encoders to try for a stream, best first, and the H.264 profile to hold
+/// them to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamPlan {
+    pub candidates: Vec<EncoderKind>,
+    pub h264_profile: Option<H264Profile>,
+    /// Whether the software encoder sends tile frames
+    pub tiles: bool,
+}
+
+impl StreamPlan {
+    /// The fallback chain cut down to what `viewer` decodes at
+    /// `width`x`height`
+    pub fn negotiate(viewer: &ViewerCapabilities, width: u32, height: u32) -> Result<Self> {
+        let candidates: Vec<EncoderKind> = EncoderKind::CHAIN
+            .into_iter()
+            .filter(|kind| viewer.decodes(kind.codec(), width, height))
+            .collect();
+        if candidates.is_empty() {
+            let codecs: Vec<&str> = viewer.codecs.iter().map(|codec| codec.name()).collect();
+            return Err(GhostLinkError::Protocol(format!(
+                "Viewer decodes none of the codecs this agent streams at {}x{} (offered: [{}])",
+                width, height, codecs.join(", ")
+            )));
+        }
+
+        Ok(Self { candidates, h264_profile: viewer.h264_profile(), tiles: viewer.tiles })
+    }
+
+    /// The next step down after `viewer` failed to decode `current`: H.264
+    /// baseline, then JPEG frames. JPEG is offered even to viewers that did
+    /// not announce it, as every viewer shows images.
+    pub fn downgrade(current: &StreamConfig, viewer: &ViewerCapabilities) -> Result<Self> {
+        let last_resort = Self { candidates: vec![EncoderKind::Software], h264_profile: None, tiles: false };
+        match current.codec {
+            StreamCodec::Tiles => Ok(last_resort),
+            StreamCodec::Jpeg | StreamCodec::Png => Err(GhostLinkError::Protocol(format!(
+                "Nothing left to fall back to from {}",
+                current.codec
+            ))),
+            StreamCodec::H264 if current.h264_profile == Some(H264Profile::Baseline) => Ok(last_resort),
+            _ if !viewer.decodes(StreamCodec::H264, current.width, current.height) => Ok(last_resort),
+            _ => {
+                let mut candidates: Vec<EncoderKind> = EncoderKind::CHAIN
+                    .into_iter()
+                    .filter(|kind| kind.codec() == StreamCodec::H264)
+                    .collect();
+                candidates.push(EncoderKind::Software);
+                Ok(Self { candidates, h264_profile: Some(H264Profile::Baseline), tiles: viewer.tiles })
+            }
+        }
+    }
+
+    /// Config confirming `encoder`, picked from this plan
+    pub fn config(&self, encoder: EncoderKind, width: u32, height: u32) -> StreamConfig {
+        let mut config = StreamConfig::new(encoder, self.h264_profile, width, height);
+        if self.tiles && encoder == EncoderKind::Software {
+            config.codec = StreamCodec::Tiles;
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::encoder_factory::select_encoder;
+    use crate::capture::{EncoderInfo, Frame, PixelFormat, VideoEncoder};
+
+    /// Encoder whose device may be missing
+    struct FakeEncoder {
+        works: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl VideoEncoder for FakeEncoder {
+        async fn initialize(&mut self, _width: u32, _height: u32, _fps: u32) -> Result<()> {
+            if self.works {
+                Ok(())
+            } else {
+                Err(GhostLinkError::Other("no device".to_string()))
+            }
+        }
+
+        async fn encode_frame(&mut self, _frame: &Frame) -> Result<Vec<u8>> {
+            Ok(vec![0; 8])
+        }
+
+        fn get_encoder_info(&self) -> EncoderInfo {
+            EncoderInfo {
+                name: "Fake".to_string(),
+                hardware_accelerated: false,
+                supported_formats: vec![PixelFormat::RGBA],
+                max_resolution: (8192, 8192),
+                profile: None,
+                skipped: Vec::new(),
+            }
+        }
+
+        fn is_healthy(&self) -> bool {
+            self.works
+        }
+    }
+
+    fn viewer(codecs: &[StreamCodec]) -> ViewerCapabilities {
+        ViewerCapabilities { codecs: codecs.to_vec(), ..ViewerCapabilities::default() }
+    }
+
+    /// Config of the encoder `plan` ends up with when only `working` encoders
+    /// work on this machine
+    async fn pick(plan: &StreamPlan, working: &[EncoderKind]) -> StreamConfig {
+        let selection = select_encoder(&plan.candidates, |kind| {
+            let works = working.contains(&kind);
+            async move { Ok(FakeEncoder { works }) }
+        })
+        .await
+        .unwrap();
+        plan.config(selection.kind, 1920, 1080)
+    }
+
+    #[tokio::test]
+    async fn test_negotiation_matrix() {
+        let everything = EncoderKind::CHAIN;
+
+        // A browser without HEVC or AV1 decoders gets H.264 from the best
+        // H.264 encoder there is
+        let plan = StreamPlan::negotiate(&viewer(&[StreamCodec::H264]), 1920, 1080).unwrap();
+        assert_eq!(plan.candidates[0], EncoderKind::NvencH264);
+        assert!(plan.candidates.iter().all(|kind| kind.codec() == StreamCodec::H264));
+        let config = pick(&plan, &everything).await;
+        assert_eq!((config.encoder, config.codec), (EncoderKind::NvencH264, StreamCodec::H264));
+        assert_eq!(config.protocol_version, PROTOCOL_VERSION);
+
+        // AV1 wins when both ends have it, H.264 when the GPU lacks it
+        let plan = StreamPlan::negotiate(&viewer(&[StreamCodec::H264, StreamCodec::Av1]), 1920, 1080).unwrap();
+        assert_eq!(pick(&plan, &everything).await.codec, StreamCodec::Av1);
+        assert!(!plan.candidates.contains(&EncoderKind::NvencH265));
+        let config = pick(&plan, &[EncoderKind::NvencH264]).await;
+        assert_eq!(config.codec, StreamCodec::H264);
+
+        // A viewer that decodes nothing the agent streams gets no stream
+        let error = StreamPlan::negotiate(&viewer(&[]), 1920, 1080).unwrap_err();
+        assert!(error.to_string().contains("decodes none"), "{}", error);
+
+        // Past the decoder's size limit only images are left
+        let small = ViewerCapabilities { max_width: Some(1920), max_height: Some(1080), ..viewer(&[StreamCodec::H264]) };
+        assert!(StreamPlan::negotiate(&small, 3840, 2160).is_err());
+        let small = ViewerCapabilities { codecs: vec![StreamCodec::H264, StreamCodec::Jpeg], ..small };
+        let plan = StreamPlan::negotiate(&small, 3840, 2160).unwrap();
+        assert_eq!(plan.candidates, [EncoderKind::Software]);
+
+        // Viewers that composite tiles get them from the software encoder only
+        let tiles = ViewerCapabilities { tiles: true, ..small };
+        let plan = StreamPlan::negotiate(&tiles, 3840, 2160).unwrap();
+        assert_eq!(pick(&plan, &everything).await.codec, StreamCodec::Tiles);
+        let plan = StreamPlan::negotiate(&tiles, 1920, 1080).unwrap();
+        assert_eq!(pick(&plan, &everything).await.codec, StreamCodec::H264);
+    }
+
+    #[tokio::test]
+    async fn test_decode_failure_downgrades_to_baseline_then_jpeg() {
+        let everything = EncoderKind::CHAIN;
+        let viewer = ViewerCapabilities {
+            h264_profiles: vec![H264Profile::Main, H264Profile::High],
+            ..viewer(&[StreamCodec::Av1, StreamCodec::H264])
+        };
+        let plan = StreamPlan::negotiate(&viewer, 1920, 1080).unwrap();
+        let config = pick(&plan, &everything).await;
+        assert_eq!((config.codec, config.h264_profile), (StreamCodec::Av1, None));
+
+        // The viewer's AV1 decoder gave up mid-session
+        let plan = StreamPlan::downgrade(&config, &viewer).unwrap();
+        let config = pick(&plan, &everything).await;
+        assert_eq!(config.codec, StreamCodec::H264);
+        assert_eq!(config.h264_profile, Some(H264Profile::Baseline));
+
+        // Then baseline failed too; with no H.264 encoder working the chain
+        // still reaches JPEG
+        let plan = StreamPlan::downgrade(&config, &viewer).unwrap();
+        assert_eq!(plan.candidates, [EncoderKind::Software]);
+        let config = pick(&plan, &[EncoderKind::Software]).await;
+        assert_eq!((config.codec, config.h264_profile), (StreamCodec::Jpeg, None));
+
+        let error = StreamPlan::downgrade(&config, &viewer).unwrap_err();
+        assert!(error.to_string().contains("Nothing left"), "{}", error);
+
+        // Tile frames step down to whole pictures
+        let tiles = StreamConfig { codec: StreamCodec::Tiles, ..config };
+        let plan = StreamPlan::downgrade(&tiles, &viewer).unwrap();
+        assert_eq!(pick(&plan, &[EncoderKind::Software]).await.codec, StreamCodec::Jpeg);
+
+        // Main profile steps down to baseline before leaving H.264
+        let main = StreamConfig::new(EncoderKind::NvencH264, Some(H264Profile::Main), 1920, 1080);
+        let plan = StreamPlan::downgrade(&main, &viewer).unwrap();
+        assert_eq!(plan.h264_profile, Some(H264Profile::Baseline));
+        assert_eq!(plan.candidates.last(), Some(&EncoderKind::Software));
+    }
+
+    #[test]
+    fn test_h264_profile_of_viewer() {
+        let mut viewer = viewer(&[StreamCodec::H264]);
+        assert_eq!(viewer.h264_profile(), None);
+        viewer.h264_profiles = vec![H264Profile::Baseline, H264Profile::Main];
+        assert_eq!(viewer.h264_profile(), Some(H264Profile::Main));
+        viewer.h264_profiles.push(H264Profile::High);
+        assert_eq!(viewer.h264_profile(), None);
+
+        let json = serde_json::json!({ "codecs": ["h264", "av1"], "h264_profiles": ["baseline"], "max_width": 1920 });
+        let viewer: ViewerCapabilities = serde_json::from_value(json).unwrap();
+        assert_eq!(viewer.codecs, [StreamCodec::H264, StreamCodec::Av1]);
+        assert!(!viewer.tiles);
+        assert_eq!(viewer.h264_profile(), Some(H264Profile::Baseline));
+        assert_eq!(viewer.max_height, None);
+    }
+}
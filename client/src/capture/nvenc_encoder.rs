@@ -6,13 +6,14 @@ use tracing::{info, warn};
 #[cfg(feature = "nvenc")]
 use ffmpeg_next as ffmpeg;
 
+use crate::capture::encoder_profile::{CodecFamily, EncoderProfile, EncoderSettings};
+#[cfg(feature = "nvenc")]
+use crate::capture::encoder_profile::RateControl;
 use crate::capture::{EncoderInfo, Frame, PixelFormat, VideoEncoder};
 use crate::error::{GhostLinkError, Result};
 
 const TARGET_FPS: u32 = 60;
 const DEFAULT_BITRATE: u32 = 3_000_000; // 3 Mbps for hardware encoding
-const NVENC_PRESET: &str = "p1"; // Fastest preset for lowest latency
-const NVENC_TUNE: &str = "ull"; // Ultra Low Latency
 
 /// NVIDIA NVENC hardware encoder for maximum 60fps performance with GPU acceleration
 pub struct NvencEncoder {
@@ -25,8 +26,11 @@ pub struct NvencEncoder {
     frame_count: u64,
     last_keyframe: u64,
     keyframe_interval: u64,
+    /// Whether the last packet out of the encoder was a keyframe
+    last_frame_keyframe: bool,
     is_initialized: bool,
     gpu_memory_type: GpuMemoryType,
+    settings: EncoderSettings,
 }
 
 #[derive(Debug, Clone)]
@@ -77,8 +81,10 @@ impl NvencEncoder {
             frame_count: 0,
             last_keyframe: 0,
             keyframe_interval: TARGET_FPS as u64 * 2, // Keyframe every 2 seconds
+            last_frame_keyframe: false,
             is_initialized: false,
             gpu_memory_type: GpuMemoryType::SystemMemory,
+            settings: EncoderProfile::LowLatency.settings(CodecFamily::Nvenc),
         }
     }
 
@@ -183,17 +189,36 @@ impl NvencEncoder {
         encoder.set_frame_rate(ffmpeg::Rational::new(fps as i32, 1));
         encoder.set_bit_rate(self.bitrate as usize);
         
-        // NVENC-specific performance options
-        encoder.set_option("preset", NVENC_PRESET)
+        // NVENC options from the active profile
+        let settings = &self.settings;
+        encoder.set_option("preset", settings.preset)
             .map_err(|e| GhostLinkError::Other(format!("Failed to set NVENC preset: {}", e)))?;
-        encoder.set_option("tune", NVENC_TUNE)
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set NVENC tune: {}", e)))?;
-        encoder.set_option("delay", "0") // Zero frame delay
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set delay: {}", e)))?;
-        encoder.set_option("zerolatency", "1") // Enable zero latency mode
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set zerolatency: {}", e)))?;
-        encoder.set_option("cbr", "1") // Constant bitrate for streaming
-            .map_err(|e| GhostLinkError::Other(format!("Failed to set CBR: {}", e)))?;
+        if let Some(tune) = settings.tune {
+            encoder.set_option("tune", tune)
+                .map_err(|e| GhostLinkError::Other(format!("Failed to set NVENC tune: {}", e)))?;
+        }
+        encoder.set_option("g", &settings.gop_length(fps).to_string())
+            .map_err(|e| GhostLinkError::Other(format!("Failed to set GOP length: {}", e)))?;
+        encoder.set_option("bf", &settings.b_frames.to_string())
+            .map_err(|e| GhostLinkError::Other(format!("Failed to set B-frames: {}", e)))?;
+        if settings.b_frames == 0 {
+            encoder.set_option("delay", "0") // Zero frame delay
+                .map_err(|e| GhostLinkError::Other(format!("Failed to set delay: {}", e)))?;
+            encoder.set_option("zerolatency", "1") // Enable zero latency mode
+                .map_err(|e| GhostLinkError::Other(format!("Failed to set zerolatency: {}", e)))?;
+        }
+        match settings.rate_control {
+            RateControl::Cbr => {
+                encoder.set_option("cbr", "1") // Constant bitrate for streaming
+                    .map_err(|e| GhostLinkError::Other(format!("Failed to set CBR: {}", e)))?;
+            }
+            RateControl::CappedCrf(cq) | RateControl::Crf(cq) => {
+                encoder.set_option("rc", "vbr")
+                    .map_err(|e| GhostLinkError::Other(format!("Failed to set rate control: {}", e)))?;
+                encoder.set_option("cq", &cq.to_string())
+                    .map_err(|e| GhostLinkError::Other(format!("Failed to set CQ: {}", e)))?;
+            }
+        }
         
         // Quality settings optimized for real-time
         match self.codec_type {
@@ -278,7 +303,12 @@ impl NvencEncoder {
         // Set frame timing and properties
         nv12_frame.set_pts(Some(self.frame_count as i64));
         
-        // Let NVENC decide keyframes based on GOP settings for better efficiency
+        // Let NVENC decide keyframes based on GOP settings for better efficiency,
+        // unless a viewer or a refresh asked for one right away
+        if self.last_keyframe == 0 {
+            nv12_frame.set_kind(ffmpeg::picture::Type::I);
+            self.last_keyframe = self.frame_count;
+        }
         
         // Submit frame to NVENC encoder
         context.ffmpeg_context.send_frame(&nv12_frame)
@@ -289,6 +319,7 @@ impl NvencEncoder {
         match context.ffmpeg_context.receive_packet(&mut packet) {
             Ok(_) => {
                 let encoded_data = packet.data().unwrap_or(&[]).to_vec();
+                self.last_frame_keyframe = packet.is_key();
                 debug!("NVENC encoded frame {} -> {} bytes (GPU accelerated)", self.frame_count, encoded_data.len());
                 Ok(encoded_data)
             }
@@ -346,7 +377,7 @@ impl VideoEncoder for NvencEncoder {
         self.width = width;
         self.height = height;
         self.fps = fps;
-        self.keyframe_interval = fps as u64 * 2; // Keyframe every 2 seconds
+        self.keyframe_interval = self.settings.gop_length(fps) as u64;
         
         #[cfg(feature = "nvenc")]
         {
@@ -400,9 +431,25 @@ impl VideoEncoder for NvencEncoder {
             hardware_accelerated: true,
             supported_formats: vec![PixelFormat::RGBA, PixelFormat::NV12],
             max_resolution: (7680, 4320), // Support up to 8K with modern GPUs
+            profile: Some(self.settings.profile),
+            skipped: Vec::new(),
         }
     }
 
+    fn apply_profile(&mut self, settings: &EncoderSettings) {
+        info!("Applying {} profile to NVENC {:?} encoder", settings.profile, self.codec_type);
+        self.settings = settings.clone();
+        self.keyframe_interval = self.settings.gop_length(self.fps) as u64;
+    }
+
+    fn force_keyframe(&mut self) {
+        self.last_keyframe = 0;
+    }
+
+    fn last_frame_was_keyframe(&self) -> bool {
+        self.last_frame_keyframe
+    }
+
     fn is_healthy(&self) -> bool {
         self.is_initialized && Self::is_available()
     }
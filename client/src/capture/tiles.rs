@@ -0,0 +1,531 @@
+//! Tile frames: only the changed parts of the screen
+//!
+//! In tile mode the software encoder sends the dirty regions of a frame
+//! instead of the whole picture. Each region is cut into tiles along a
+//! `DEFAULT_TILE_SIZE` grid, and a cheap look at each tile's colors picks its
+//! encoding: a single color becomes a fill, a handful of colors (text,
+//! window chrome) goes to PNG, and anything busier to JPEG. Neighbouring
+//! tiles in a row that chose alike are encoded together. The viewer paints
+//! the tiles over the picture it already shows, in the order they come, so
+//! a frame covering the whole screen is a keyframe; that is also how the
+//! periodic full refresh arrives.
+//!
+//! Payload, little-endian: `GFTL`, the picture's width and height and the
+//! tile count as u32, then per tile its x, y, width and height as u16, its
+//! kind as u8, its data length as u32 and the data. Solid tiles carry their
+//! RGB color.
+
+use image::RgbaImage;
+use std::io::Cursor;
+
+use super::frame_diff::{DirtyRect, DEFAULT_TILE_SIZE};
+use super::{Frame, PixelFormat};
+use crate::error::{GhostLinkError, Result};
+
+/// Starts every tile frame payload
+pub const TILE_MAGIC: [u8; 4] = *b"GFTL";
+
+/// Magic, picture size and tile count
+const FRAME_HEADER_LEN: usize = 16;
+
+/// Position, size, kind and data length
+const TILE_HEADER_LEN: usize = 13;
+
+/// Distinct colors up to which a tile counts as flat and goes to PNG
+const FLAT_COLOR_LIMIT: usize = 32;
+
+/// How a tile's pixels are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileKind {
+    /// Photographic content, lossy
+    Jpeg = 0,
+    /// Flat content such as text and window chrome, lossless
+    Png = 1,
+    /// A single color
+    Solid = 2,
+}
+
+impl TileKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(TileKind::Jpeg),
+            1 => Some(TileKind::Png),
+            2 => Some(TileKind::Solid),
+            _ => None,
+        }
+    }
+}
+
+/// One encoded region of a tile frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tile {
+    pub rect: DirtyRect,
+    pub kind: TileKind,
+    pub data: Vec<u8>,
+}
+
+/// Regions to paint over the viewer's current picture, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileFrame {
+    pub width: u32,
+    pub height: u32,
+    pub tiles: Vec<Tile>,
+}
+
+impl TileFrame {
+    /// Encode the `regions` of `frame`; `lossless` sends photographic tiles
+    /// as PNG too
+    pub fn encode_regions(frame: &Frame, regions: &[DirtyRect], jpeg_quality: u8, lossless: bool) -> Result<Self> {
+        if frame.width > u16::MAX as u32 || frame.height > u16::MAX as u32 {
+            return Err(GhostLinkError::Encode(format!(
+                "{}x{} is too large for tile frames",
+                frame.width, frame.height
+            )));
+        }
+
+        let mut tiles = Vec::new();
+        for region in regions {
+            for (rect, kind, rgb) in runs(frame, clip(region, frame.width, frame.height))? {
+                let kind = if lossless && kind == TileKind::Jpeg { TileKind::Png } else { kind };
+                let data = match kind {
+                    TileKind::Solid => rgb[..3].to_vec(),
+                    TileKind::Png => encode_png(&rgb, rect)?,
+                    TileKind::Jpeg => encode_jpeg(&rgb, rect, jpeg_quality)?,
+                };
+                tiles.push(Tile { rect, kind, data });
+            }
+        }
+
+        Ok(Self { width: frame.width, height: frame.height, tiles })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let data_len: usize = self.tiles.iter().map(|tile| TILE_HEADER_LEN + tile.data.len()).sum();
+        let mut out = Vec::with_capacity(FRAME_HEADER_LEN + data_len);
+        out.extend_from_slice(&TILE_MAGIC);
+        out.extend_from_slice(&self.width.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.extend_from_slice(&(self.tiles.len() as u32).to_le_bytes());
+        for tile in &self.tiles {
+            for value in [tile.rect.x, tile.rect.y, tile.rect.width, tile.rect.height] {
+                out.extend_from_slice(&(value as u16).to_le_bytes());
+            }
+            out.push(tile.kind as u8);
+            out.extend_from_slice(&(tile.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&tile.data);
+        }
+        out
+    }
+
+    /// Read a tile frame payload, rejecting tiles outside the picture
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let invalid = |what: &str| GhostLinkError::Protocol(format!("Invalid tile frame: {}", what));
+        if data.len() < FRAME_HEADER_LEN || data[..4] != TILE_MAGIC {
+            return Err(invalid("no tile frame header"));
+        }
+        let u16_at = |bytes: &[u8], at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as u32;
+        let u32_at = |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+        let (width, height, count) = (u32_at(data, 4), u32_at(data, 8), u32_at(data, 12) as usize);
+
+        let mut tiles = Vec::with_capacity(count.min(data.len() / TILE_HEADER_LEN));
+        let mut rest = &data[FRAME_HEADER_LEN..];
+        for _ in 0..count {
+            let header = rest.get(..TILE_HEADER_LEN).ok_or_else(|| invalid("truncated tile header"))?;
+            let rect = DirtyRect {
+                x: u16_at(header, 0),
+                y: u16_at(header, 2),
+                width: u16_at(header, 4),
+                height: u16_at(header, 6),
+            };
+            let kind = TileKind::from_byte(header[8]).ok_or_else(|| invalid("unknown tile kind"))?;
+            let len = u32_at(header, 9) as usize;
+            let body = rest[TILE_HEADER_LEN..].get(..len).ok_or_else(|| invalid("truncated tile data"))?;
+            rest = &rest[TILE_HEADER_LEN + len..];
+
+            if rect.width == 0 || rect.height == 0 || rect.x + rect.width > width || rect.y + rect.height > height {
+                return Err(invalid("tile outside the picture"));
+            }
+            if kind == TileKind::Solid && len != 3 {
+                return Err(invalid("solid tile without an RGB color"));
+            }
+            tiles.push(Tile { rect, kind, data: body.to_vec() });
+        }
+        if !rest.is_empty() {
+            return Err(invalid("data after the last tile"));
+        }
+
+        Ok(Self { width, height, tiles })
+    }
+
+    /// Paint the tiles over `canvas` in order, starting from a black
+    /// picture when the size changed
+    pub fn composite(&self, canvas: &mut RgbaImage) -> Result<()> {
+        if canvas.dimensions() != (self.width, self.height) {
+            *canvas = RgbaImage::new(self.width, self.height);
+        }
+        for tile in &self.tiles {
+            let rect = tile.rect;
+            match tile.kind {
+                TileKind::Solid => {
+                    let color = image::Rgba([tile.data[0], tile.data[1], tile.data[2], 255]);
+                    for y in rect.y..rect.y + rect.height {
+                        for x in rect.x..rect.x + rect.width {
+                            canvas.put_pixel(x, y, color);
+                        }
+                    }
+                }
+                TileKind::Jpeg | TileKind::Png => {
+                    let picture = image::load_from_memory(&tile.data)
+                        .map_err(|e| GhostLinkError::Encode(format!("Failed to decode {:?} tile: {}", tile.kind, e)))?
+                        .to_rgba8();
+                    if picture.dimensions() != (rect.width, rect.height) {
+                        return Err(GhostLinkError::Protocol(format!(
+                            "{:?} tile is {}x{}, its rectangle {}x{}",
+                            tile.kind, picture.width(), picture.height(), rect.width, rect.height
+                        )));
+                    }
+                    image::imageops::replace(canvas, &picture, rect.x as i64, rect.y as i64);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pick the encoding for a tile from its RGB pixels: one color is a fill, a
+/// few are flat UI that PNG keeps sharp and small, anything busier is
+/// photographic and goes to JPEG
+pub fn classify(rgb: &[u8]) -> TileKind {
+    let mut colors: Vec<[u8; 3]> = Vec::with_capacity(FLAT_COLOR_LIMIT);
+    let mut last = None;
+    for pixel in rgb.chunks_exact(3) {
+        let color = [pixel[0], pixel[1], pixel[2]];
+        // Flat content comes in runs, which spares most of the lookups
+        if last == Some(color) {
+            continue;
+        }
+        last = Some(color);
+        if !colors.contains(&color) {
+            if colors.len() == FLAT_COLOR_LIMIT {
+                return TileKind::Jpeg;
+            }
+            colors.push(color);
+        }
+    }
+    if colors.len() <= 1 {
+        TileKind::Solid
+    } else {
+        TileKind::Png
+    }
+}
+
+/// `region` cut to the picture
+fn clip(region: &DirtyRect, width: u32, height: u32) -> DirtyRect {
+    let x = region.x.min(width);
+    let y = region.y.min(height);
+    DirtyRect {
+        x,
+        y,
+        width: region.width.min(width - x),
+        height: region.height.min(height - y),
+    }
+}
+
+/// Tiles of `region`, with neighbours in a row that classified alike (and
+/// for fills, share the color) merged, each with its RGB pixels
+fn runs(frame: &Frame, region: DirtyRect) -> Result<Vec<(DirtyRect, TileKind, Vec<u8>)>> {
+    let mut runs = Vec::new();
+    let (right, bottom) = (region.x + region.width, region.y + region.height);
+
+    for y in (region.y..bottom).step_by(DEFAULT_TILE_SIZE as usize) {
+        let height = DEFAULT_TILE_SIZE.min(bottom - y);
+        let mut run: Option<(DirtyRect, TileKind, [u8; 3])> = None;
+        for x in (region.x..right).step_by(DEFAULT_TILE_SIZE as usize) {
+            let rect = DirtyRect { x, y, width: DEFAULT_TILE_SIZE.min(right - x), height };
+            let rgb = crop_rgb(frame, rect)?;
+            let kind = classify(&rgb);
+            let color = [rgb[0], rgb[1], rgb[2]];
+            match &mut run {
+                Some((merged, merged_kind, merged_color))
+                    if *merged_kind == kind && (kind != TileKind::Solid || *merged_color == color) =>
+                {
+                    merged.width += rect.width;
+                }
+                _ => runs.extend(run.replace((rect, kind, color))),
+            }
+        }
+        runs.extend(run);
+    }
+
+    runs.into_iter()
+        .map(|(rect, kind, _)| Ok((rect, kind, crop_rgb(frame, rect)?)))
+        .collect()
+}
+
+/// The RGB pixels of `rect`, row by row
+fn crop_rgb(frame: &Frame, rect: DirtyRect) -> Result<Vec<u8>> {
+    let (bytes_per_pixel, swapped) = match frame.pixel_format {
+        PixelFormat::RGBA => (4, false),
+        PixelFormat::BGRA => (4, true),
+        PixelFormat::RGB => (3, false),
+        PixelFormat::BGR => (3, true),
+        format => {
+            return Err(GhostLinkError::Encode(format!("Tile frames need packed RGB pixels, not {:?}", format)));
+        }
+    };
+    let stride = (frame.stride as usize).max(frame.width as usize * bytes_per_pixel);
+    let row_len = rect.width as usize * bytes_per_pixel;
+
+    let mut rgb = Vec::with_capacity(rect.width as usize * rect.height as usize * 3);
+    for row in rect.y..rect.y + rect.height {
+        let start = row as usize * stride + rect.x as usize * bytes_per_pixel;
+        let line = frame.data.get(start..start + row_len)
+            .ok_or_else(|| GhostLinkError::Encode("Frame data is shorter than its size".to_string()))?;
+        for pixel in line.chunks_exact(bytes_per_pixel) {
+            if swapped {
+                rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            } else {
+                rgb.extend_from_slice(&pixel[..3]);
+            }
+        }
+    }
+    Ok(rgb)
+}
+
+fn encode_jpeg(rgb: &[u8], rect: DirtyRect, quality: u8) -> Result<Vec<u8>> {
+    let mut jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut Cursor::new(&mut jpeg), quality)
+        .encode(rgb, rect.width, rect.height, image::ColorType::Rgb8)
+        .map_err(|e| GhostLinkError::Encode(format!("JPEG tile encoding failed: {}", e)))?;
+    Ok(jpeg)
+}
+
+fn encode_png(rgb: &[u8], rect: DirtyRect) -> Result<Vec<u8>> {
+    let mut png_data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(Cursor::new(&mut png_data), rect.width, rect.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Fast);
+        let mut writer = encoder.write_header().map_err(|e| GhostLinkError::Encode(e.to_string()))?;
+        writer.write_image_data(rgb).map_err(|e| GhostLinkError::Encode(e.to_string()))?;
+    }
+    Ok(png_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::frame_diff::{FrameChange, FrameDiffer};
+    use std::time::{Duration, Instant};
+
+    /// Cheap deterministic noise
+    fn next(state: &mut u64) -> u8 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 32) as u8
+    }
+
+    /// BGRA frame of one color, with four bytes of row padding
+    fn frame(width: u32, height: u32, [r, g, b]: [u8; 3]) -> Frame {
+        let stride = width * 4 + 4;
+        let mut data = vec![0; (stride * height) as usize];
+        for row in data.chunks_exact_mut(stride as usize) {
+            for pixel in row[..width as usize * 4].chunks_exact_mut(4) {
+                pixel.copy_from_slice(&[b, g, r, 0]);
+            }
+        }
+        Frame { data, width, height, pixel_format: PixelFormat::BGRA, stride, timestamp: 0 }
+    }
+
+    fn fill(frame: &mut Frame, rect: DirtyRect, mut color: impl FnMut(u32, u32) -> [u8; 3]) {
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                let [r, g, b] = color(x, y);
+                let at = (y * frame.stride + x * 4) as usize;
+                frame.data[at..at + 4].copy_from_slice(&[b, g, r, 0]);
+            }
+        }
+    }
+
+    /// Black-on-white glyph-like strokes
+    fn text(x: u32, y: u32) -> [u8; 3] {
+        if (x / 3 + y / 5).is_multiple_of(4) || (x * 7 + y).is_multiple_of(11) { [20, 20, 20] } else { [250, 250, 250] }
+    }
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> DirtyRect {
+        DirtyRect { x, y, width, height }
+    }
+
+    #[test]
+    fn test_tile_heuristic() {
+        let solid = [40u8, 90, 200].repeat(64 * 64);
+        assert_eq!(classify(&solid), TileKind::Solid);
+
+        let ui: Vec<u8> = (0..64 * 64).flat_map(|i| text(i % 64, i / 64)).collect();
+        assert_eq!(classify(&ui), TileKind::Png);
+
+        let mut state = 0x9E37_79B9_7F4A_7C15;
+        let photo: Vec<u8> = (0..64 * 64 * 3).map(|_| next(&mut state)).collect();
+        assert_eq!(classify(&photo), TileKind::Jpeg);
+
+        // A window over a photo: the tiles in the window go lossless, the
+        // photo tiles around it lossy, and the solid title bar is one fill
+        let mut screen = frame(256, 128, [0, 0, 0]);
+        fill(&mut screen, rect(0, 0, 256, 128), |_, _| [next(&mut state), next(&mut state), next(&mut state)]);
+        fill(&mut screen, rect(64, 0, 128, 64), |_, _| [0, 60, 160]);
+        fill(&mut screen, rect(64, 64, 128, 64), text);
+        let tiles = TileFrame::encode_regions(&screen, &[rect(0, 0, 256, 128)], 80, false).unwrap();
+        let layout: Vec<(DirtyRect, TileKind)> = tiles.tiles.iter().map(|tile| (tile.rect, tile.kind)).collect();
+        assert_eq!(layout, [
+            (rect(0, 0, 64, 64), TileKind::Jpeg),
+            (rect(64, 0, 128, 64), TileKind::Solid),
+            (rect(192, 0, 64, 64), TileKind::Jpeg),
+            (rect(0, 64, 64, 64), TileKind::Jpeg),
+            (rect(64, 64, 128, 64), TileKind::Png),
+            (rect(192, 64, 64, 64), TileKind::Jpeg),
+        ]);
+        assert_eq!(tiles.tiles[1].data, [0, 60, 160]);
+
+        let lossless = TileFrame::encode_regions(&screen, &[rect(0, 0, 256, 128)], 80, true).unwrap();
+        assert!(lossless.tiles.iter().all(|tile| tile.kind != TileKind::Jpeg));
+
+        let planar = Frame { pixel_format: PixelFormat::NV12, ..screen };
+        assert!(TileFrame::encode_regions(&planar, &[rect(0, 0, 64, 64)], 80, false).is_err());
+    }
+
+    #[test]
+    fn test_compositing_order() {
+        // A full frame, then a region that changed
+        let mut screen = frame(192, 96, [200, 200, 200]);
+        fill(&mut screen, rect(0, 0, 96, 96), text);
+        let keyframe = TileFrame::encode_regions(&screen, &[rect(0, 0, 192, 96)], 90, false).unwrap();
+        fill(&mut screen, rect(128, 32, 64, 32), |_, _| [255, 0, 0]);
+        let delta = TileFrame::encode_regions(&screen, &[rect(128, 0, 64, 64)], 90, false).unwrap();
+
+        let bytes = delta.to_bytes();
+        assert!(bytes.starts_with(&TILE_MAGIC));
+        assert_eq!(TileFrame::parse(&bytes).unwrap(), delta);
+
+        let mut canvas = RgbaImage::new(1, 1);
+        keyframe.composite(&mut canvas).unwrap();
+        delta.composite(&mut canvas).unwrap();
+        assert_eq!(canvas.dimensions(), (192, 96));
+        assert_eq!(canvas.get_pixel(150, 40).0, [255, 0, 0, 255]);
+        assert_eq!(canvas.get_pixel(150, 10).0, [200, 200, 200, 255]);
+        assert_eq!(&canvas.get_pixel(3, 0).0[..3], &text(3, 0)[..]);
+
+        // Out of order, the older full frame paints over the change
+        let mut stale = RgbaImage::new(192, 96);
+        delta.composite(&mut stale).unwrap();
+        keyframe.composite(&mut stale).unwrap();
+        assert_eq!(stale.get_pixel(150, 40).0, [200, 200, 200, 255]);
+
+        // Within a frame, a later tile wins where tiles overlap
+        let overlapping = TileFrame {
+            width: 192,
+            height: 96,
+            tiles: vec![
+                Tile { rect: rect(0, 0, 64, 64), kind: TileKind::Solid, data: vec![0, 0, 255] },
+                Tile { rect: rect(32, 32, 64, 64), kind: TileKind::Solid, data: vec![0, 255, 0] },
+            ],
+        };
+        overlapping.composite(&mut canvas).unwrap();
+        assert_eq!(canvas.get_pixel(10, 10).0, [0, 0, 255, 255]);
+        assert_eq!(canvas.get_pixel(40, 40).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_invalid_payloads_are_rejected() {
+        let frame = TileFrame {
+            width: 64,
+            height: 64,
+            tiles: vec![Tile { rect: rect(32, 0, 32, 64), kind: TileKind::Solid, data: vec![1, 2, 3] }],
+        };
+        let bytes = frame.to_bytes();
+        assert!(TileFrame::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(TileFrame::parse(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(TileFrame::parse(b"\xff\xd8\xff").is_err());
+
+        let outside = TileFrame { width: 48, ..frame.clone() };
+        assert!(TileFrame::parse(&outside.to_bytes()).unwrap_err().to_string().contains("outside"));
+        let mut unknown = bytes;
+        unknown[FRAME_HEADER_LEN + 8] = 9;
+        assert!(TileFrame::parse(&unknown).is_err());
+    }
+
+    /// A desktop in use: a photo wallpaper, a text editor being typed
+    /// into and a clock; `each` sees the screen after every frame's changes
+    fn desktop_sequence(width: u32, height: u32, frames: u32, mut each: impl FnMut(&Frame)) {
+        let mut state = 0x2545_F491_4F6C_DD1D;
+        let mut screen = frame(width, height, [0, 0, 0]);
+        fill(&mut screen, rect(0, 0, width, height), |x, y| {
+            let grain = next(&mut state) / 32;
+            [(x * 255 / width) as u8 / 2 + grain, (y * 255 / height) as u8 / 2 + grain, 120 + grain]
+        });
+        let editor = rect(width / 8, height / 8, width * 3 / 4, height * 3 / 4);
+        fill(&mut screen, editor, |_, _| [250, 250, 250]);
+        fill(&mut screen, rect(editor.x, editor.y, editor.width, 32), |_, _| [45, 45, 60]);
+
+        for i in 0..frames {
+            // One character per frame, wrapping to the next line
+            let glyph = rect(editor.x + 16 + i % 40 * 10, editor.y + 48 + i / 40 * 18, 10, 16);
+            fill(&mut screen, glyph, text);
+            // The clock ticks every second
+            if i.is_multiple_of(30) {
+                let second = i / 30;
+                fill(&mut screen, rect(width - 96, height - 24, 80, 16), |x, y| text(x + second, y));
+            }
+            each(&screen);
+        }
+    }
+
+    /// Full-frame JPEG, as the software encoder sends without tile mode,
+    /// against tile frames over the dirty regions the differ finds:
+    ///
+    /// ```text
+    /// cargo test --release -p ghostlink-client bench_tiles_against_full_frames -- --nocapture
+    /// ```
+    #[test]
+    fn bench_tiles_against_full_frames() {
+        const FPS: u32 = 30;
+        let (width, height, frames) = if cfg!(debug_assertions) { (640, 360, 60) } else { (3840, 2160, 300) };
+        let screen = rect(0, 0, width, height);
+
+        let mut full_bytes = 0;
+        let mut full_time = Duration::ZERO;
+        desktop_sequence(width, height, frames, |frame| {
+            let started = Instant::now();
+            let rgb = crop_rgb(frame, screen).unwrap();
+            full_bytes += encode_jpeg(&rgb, screen, 80).unwrap().len();
+            full_time += started.elapsed();
+        });
+
+        // Long enough a refresh interval that only the first frame is full
+        let mut differ = FrameDiffer::new(DEFAULT_TILE_SIZE, Duration::from_secs(3600));
+        let mut refresh_bytes = 0;
+        let mut delta_bytes = 0;
+        let mut tile_time = Duration::ZERO;
+        desktop_sequence(width, height, frames, |frame| {
+            let started = Instant::now();
+            let (regions, total) = match differ.diff(frame) {
+                FrameChange::Unchanged => return,
+                FrameChange::Partial(regions) => (regions, &mut delta_bytes),
+                FrameChange::Full => (vec![screen], &mut refresh_bytes),
+            };
+            *total += TileFrame::encode_regions(frame, &regions, 80, false).unwrap().to_bytes().len();
+            tile_time += started.elapsed();
+        });
+
+        let kbps = |bytes: usize| bytes as u64 * 8 * FPS as u64 / frames as u64 / 1000;
+        println!(
+            "{}x{}, {} frames: full frames {} kbit/s in {:?}/frame; tiles {} kbit/s after a {} KiB refresh, in {:?}/frame",
+            width, height, frames,
+            kbps(full_bytes), full_time / frames,
+            kbps(delta_bytes), refresh_bytes / 1024, tile_time / frames,
+        );
+        assert!((refresh_bytes + delta_bytes) * 5 < full_bytes);
+        assert!(kbps(delta_bytes) < 1000);
+    }
+}
@@ -7,10 +7,11 @@ use async_trait::async_trait;
 use tracing::{info, warn};
 
 use crate::error::{Result, GhostLinkError, CaptureError};
-use crate::capture::{Frame, DisplayInfo, ScreenCapturer};
+use crate::capture::{BufferPath, Frame, DisplayInfo, ScreenCapturer};
 
-use super::portal::{ScreenCastPortal, PortalSession};
-use super::pipewire::{PipeWireRecorder, PipeWireStream};
+use super::dmabuf::DmaBufFrame;
+use super::portal::{self, ScreenCastPortal, PortalSession};
+use super::pipewire::{CapturedFrame, PipeWireRecorder, PipeWireStream};
 use super::{CompositorType, detect_compositor};
 
 /// Wayland-native screen capturer using XDG Portal + PipeWire
@@ -31,6 +32,10 @@ pub struct WaylandPortalCapturer {
     restore_token: Option<String>,
     /// Initialization state
     is_initialized: bool,
+    /// DMA-BUF of the last frame, until an encoder takes it
+    dmabuf: Option<DmaBufFrame>,
+    /// A stream changed size since the capture loop last asked
+    display_changed: bool,
 }
 
 impl WaylandPortalCapturer {
@@ -55,6 +60,8 @@ impl WaylandPortalCapturer {
             capture_cursor: true,
             restore_token: None,
             is_initialized: false,
+            dmabuf: None,
+            display_changed: false,
         })
     }
 
@@ -80,11 +87,20 @@ impl WaylandPortalCapturer {
         // Create portal connection
         let portal = ScreenCastPortal::new()?;
 
-        // Request screen capture (this may show a permission dialog)
-        let session = portal.request_screen_capture(
-            self.capture_cursor,
-            self.restore_token.as_deref(),
-        )?;
+        // Request screen capture (this may show a permission dialog). Ask for
+        // input access in the same dialog when the portal supports it.
+        let session = if portal.supports_remote_desktop() {
+            portal.request_remote_desktop(
+                self.capture_cursor,
+                self.restore_token.as_deref(),
+            )?
+        } else {
+            warn!("RemoteDesktop portal unavailable; input injection will need uinput");
+            portal.request_screen_capture(
+                self.capture_cursor,
+                self.restore_token.as_deref(),
+            )?
+        };
 
         info!("Portal session established with {} streams", session.streams.len());
 
@@ -108,12 +124,34 @@ impl WaylandPortalCapturer {
             self.recorders.push(recorder);
         }
 
+        portal::publish_session(&session);
         self.session = Some(session);
         self.is_initialized = true;
 
         info!("Wayland capture initialized with {} displays", self.displays.len());
         Ok(())
     }
+
+    /// Keep the frame's DMA-BUF for an encoder that imports it, and the
+    /// display's size in step with its stream
+    fn frame_from(&mut self, mut captured: CapturedFrame) -> Frame {
+        // The portal only reports stream sizes on start; after a resolution
+        // change the stream's negotiated size is the one to go by
+        if let Some(info) = self.displays.get_mut(self.selected_display) {
+            if (info.width, info.height) != (captured.width, captured.height) {
+                info!(
+                    "PipeWire stream of display {} resized from {}x{} to {}x{}",
+                    info.id, info.width, info.height, captured.width, captured.height
+                );
+                info.width = captured.width;
+                info.height = captured.height;
+                self.display_changed = true;
+            }
+        }
+
+        self.dmabuf = captured.dmabuf.take();
+        captured.into_frame()
+    }
 }
 
 #[async_trait]
@@ -142,20 +180,24 @@ impl ScreenCapturer for WaylandPortalCapturer {
                 reason: format!("Invalid display index: {}", self.selected_display),
             }))?;
 
+        // A buffer left from an unchanged frame goes back to PipeWire
+        self.dmabuf = None;
+
         // Capture with 100ms timeout
-        match recorder.capture_frame(100)? {
-            Some(captured) => Ok(captured.to_frame()),
+        let captured = match recorder.capture_frame(100)? {
+            Some(captured) => captured,
             None => {
                 // No new frame - return a placeholder or wait
                 // For now, try again with longer timeout
                 match recorder.capture_frame(500)? {
-                    Some(captured) => Ok(captured.to_frame()),
-                    None => Err(GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
+                    Some(captured) => captured,
+                    None => return Err(GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
                         reason: "No frame available".into(),
                     })),
                 }
             }
-        }
+        };
+        Ok(self.frame_from(captured))
     }
 
     fn get_display_info(&self) -> Vec<DisplayInfo> {
@@ -170,6 +212,7 @@ impl ScreenCapturer for WaylandPortalCapturer {
             }));
         }
         self.selected_display = idx;
+        portal::select_shared_stream(idx);
         info!("Selected display {}", display_id);
         Ok(())
     }
@@ -194,14 +237,38 @@ impl ScreenCapturer for WaylandPortalCapturer {
         self.is_initialized && !self.recorders.is_empty()
     }
 
+    fn cursor_in_frames(&self) -> bool {
+        // The portal draws the cursor into the stream when asked to
+        self.capture_cursor
+    }
+
+    fn buffer_path(&self) -> BufferPath {
+        self.recorders
+            .get(self.selected_display)
+            .map_or(BufferPath::Shm, PipeWireRecorder::buffer_path)
+    }
+
+    fn take_dmabuf(&mut self) -> Option<DmaBufFrame> {
+        self.dmabuf.take()
+    }
+
+    fn take_display_change(&mut self) -> bool {
+        std::mem::take(&mut self.display_changed)
+    }
+
     async fn cleanup(&mut self) -> Result<()> {
         info!("Cleaning up Wayland portal capturer");
 
+        // No buffer may outlive its pipeline
+        self.dmabuf = None;
+
         // Drop recorders (stops GStreamer pipelines)
         self.recorders.clear();
 
         // Drop session (closes portal session)
-        self.session = None;
+        if let Some(session) = self.session.take() {
+            portal::clear_shared_session(&session.session_path);
+        }
 
         self.is_initialized = false;
         self.displays.clear();
@@ -253,6 +320,22 @@ impl ScreenCapturer for WaylandCapturer {
         self.inner.is_healthy()
     }
 
+    fn cursor_in_frames(&self) -> bool {
+        self.inner.cursor_in_frames()
+    }
+
+    fn buffer_path(&self) -> BufferPath {
+        self.inner.buffer_path()
+    }
+
+    fn take_dmabuf(&mut self) -> Option<DmaBufFrame> {
+        self.inner.take_dmabuf()
+    }
+
+    fn take_display_change(&mut self) -> bool {
+        self.inner.take_display_change()
+    }
+
     async fn cleanup(&mut self) -> Result<()> {
         self.inner.cleanup().await
     }
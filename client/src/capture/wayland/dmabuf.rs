@@ -0,0 +1,457 @@
+//! DMA-BUF frames from PipeWire
+//!
+//! Compositors that can export DMA-BUFs hand frames over without copying
+//! them into PipeWire's shared memory. The recorder maps such a buffer once
+//! for the frame differ and software encoders, and encoders that import
+//! DMA-BUFs get the file descriptor itself. Only linear buffers can be
+//! mapped: a tiled or compressed modifier, or a buffer that fails to map,
+//! sends the stream back to shared memory.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use gstreamer as gst;
+use gstreamer_allocators as gst_allocators;
+use tracing::warn;
+
+use super::pipewire::PipeWirePixelFormat;
+use crate::capture::BufferPath;
+
+/// Plain row-major layout, the only one the CPU can read directly
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// `struct dma_buf_sync` flags, from `linux/dma-buf.h`
+const DMA_BUF_SYNC_READ: u64 = 1;
+const DMA_BUF_SYNC_START: u64 = 0;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+/// `_IOW('b', 0, struct dma_buf_sync)`
+const DMA_BUF_IOCTL_SYNC: u64 = 0x4008_6200;
+
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+/// Packed 32-bit DRM formats the capture path understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrmFormat {
+    Xrgb8888,
+    Argb8888,
+    Xbgr8888,
+    Abgr8888,
+}
+
+impl DrmFormat {
+    /// In order of preference; compositors almost always scan out XRGB8888
+    pub const ALL: [DrmFormat; 4] = [
+        DrmFormat::Xrgb8888,
+        DrmFormat::Argb8888,
+        DrmFormat::Xbgr8888,
+        DrmFormat::Abgr8888,
+    ];
+
+    pub fn fourcc(self) -> u32 {
+        match self {
+            DrmFormat::Xrgb8888 => fourcc(b"XR24"),
+            DrmFormat::Argb8888 => fourcc(b"AR24"),
+            DrmFormat::Xbgr8888 => fourcc(b"XB24"),
+            DrmFormat::Abgr8888 => fourcc(b"AB24"),
+        }
+    }
+
+    pub fn from_fourcc(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.fourcc() == code)
+    }
+
+    /// GStreamer's name for the same byte order in memory; DRM formats
+    /// name the little-endian word, GStreamer the bytes
+    pub fn video_format(self) -> &'static str {
+        match self {
+            DrmFormat::Xrgb8888 => "BGRx",
+            DrmFormat::Argb8888 => "BGRA",
+            DrmFormat::Xbgr8888 => "RGBx",
+            DrmFormat::Abgr8888 => "RGBA",
+        }
+    }
+
+    pub fn from_video_format(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.video_format() == name)
+    }
+
+    pub fn pixel_format(self) -> PipeWirePixelFormat {
+        match self {
+            DrmFormat::Xrgb8888 => PipeWirePixelFormat::BGRx,
+            DrmFormat::Argb8888 => PipeWirePixelFormat::BGRA,
+            DrmFormat::Xbgr8888 => PipeWirePixelFormat::RGBx,
+            DrmFormat::Abgr8888 => PipeWirePixelFormat::RGBA,
+        }
+    }
+
+    /// The `drm-format` caps field: the fourcc, followed by the modifier
+    /// unless it is linear
+    pub fn drm_string(self, modifier: u64) -> String {
+        let code = self.fourcc().to_le_bytes();
+        let name: String = code.iter().map(|&byte| byte as char).collect();
+        if modifier == DRM_FORMAT_MOD_LINEAR {
+            name
+        } else {
+            format!("{}:{:#018x}", name, modifier)
+        }
+    }
+
+    /// Parse a `drm-format` caps field into format and modifier
+    pub fn parse_drm_string(value: &str) -> Option<(Self, u64)> {
+        let (name, modifier) = match value.split_once(':') {
+            Some((name, modifier)) => {
+                let digits = modifier.strip_prefix("0x").unwrap_or(modifier);
+                (name, u64::from_str_radix(digits, 16).ok()?)
+            }
+            None => (value, DRM_FORMAT_MOD_LINEAR),
+        };
+        let code: [u8; 4] = name.as_bytes().try_into().ok()?;
+        Some((Self::from_fourcc(u32::from_le_bytes(code))?, modifier))
+    }
+}
+
+/// What a PipeWire stream settled on, as read off its negotiated caps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamCaps {
+    pub width: u32,
+    pub height: u32,
+    /// Raw video format, or `DMA_DRM` when `drm_format` names it
+    pub format: String,
+    pub drm_format: Option<String>,
+    /// Buffers arrive as DMA-BUFs (the `memory:DMABuf` caps feature)
+    pub dmabuf: bool,
+}
+
+/// How frames of a negotiated stream are laid out and delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamLayout {
+    pub width: u32,
+    pub height: u32,
+    pub format: DrmFormat,
+    pub path: BufferPath,
+}
+
+/// Outcome of checking a stream's negotiated caps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Negotiated {
+    Ready(StreamLayout),
+    /// The DMA-BUFs can't be used; restart the stream on shared memory
+    Renegotiate { reason: String },
+}
+
+/// Chooses between DMA-BUF and shared-memory buffers for one stream
+#[derive(Debug, Clone)]
+pub struct DmaBufNegotiation {
+    allowed: bool,
+}
+
+impl DmaBufNegotiation {
+    pub fn new(allowed: bool) -> Self {
+        Self { allowed }
+    }
+
+    /// Whether DMA-BUFs should be offered to PipeWire
+    pub fn dmabuf_allowed(&self) -> bool {
+        self.allowed
+    }
+
+    /// Check the caps the stream settled on
+    pub fn accept(&mut self, caps: &StreamCaps) -> Negotiated {
+        if !caps.dmabuf {
+            let format = DrmFormat::from_video_format(&caps.format).unwrap_or_else(|| {
+                warn!("Unknown pixel format: {}, assuming BGRx", caps.format);
+                DrmFormat::Xrgb8888
+            });
+            return Negotiated::Ready(StreamLayout {
+                width: caps.width,
+                height: caps.height,
+                format,
+                path: BufferPath::Shm,
+            });
+        }
+
+        // Caps from before GStreamer 1.24 name a plain video format and
+        // imply a linear buffer
+        let parsed = match caps.drm_format.as_deref() {
+            Some(drm_format) if caps.format == "DMA_DRM" => DrmFormat::parse_drm_string(drm_format),
+            _ => DrmFormat::from_video_format(&caps.format).map(|format| (format, DRM_FORMAT_MOD_LINEAR)),
+        };
+        let reason = match parsed {
+            _ if !self.allowed => "DMA-BUFs were not offered".to_string(),
+            Some((format, DRM_FORMAT_MOD_LINEAR)) => {
+                return Negotiated::Ready(StreamLayout {
+                    width: caps.width,
+                    height: caps.height,
+                    format,
+                    path: BufferPath::DmaBuf,
+                });
+            }
+            Some((_, modifier)) => format!("modifier {:#x} is not linear", modifier),
+            None => format!(
+                "unsupported format {}",
+                caps.drm_format.as_deref().unwrap_or(&caps.format)
+            ),
+        };
+        self.allowed = false;
+        Negotiated::Renegotiate { reason }
+    }
+
+    /// A DMA-BUF could not be mapped; stay on shared memory from now on
+    pub fn import_failed(&mut self) {
+        self.allowed = false;
+    }
+}
+
+/// The pixel plane of a DMA-BUF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaBufPlane {
+    pub fd: RawFd,
+    /// Where the first row starts in the buffer
+    pub offset: usize,
+    /// Bytes from one row to the next
+    pub stride: usize,
+}
+
+/// A frame still in its DMA-BUF, held out of PipeWire's buffer pool until
+/// dropped
+#[derive(Debug, Clone)]
+pub struct DmaBufFrame {
+    buffer: gst::Buffer,
+    pub plane: DmaBufPlane,
+    pub width: u32,
+    pub height: u32,
+    pub format: DrmFormat,
+    pub modifier: u64,
+}
+
+impl DmaBufFrame {
+    /// Wrap a buffer of a stream negotiated for DMA-BUFs
+    pub fn from_buffer(buffer: gst::Buffer, layout: &StreamLayout) -> io::Result<Self> {
+        if buffer.n_memory() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected one memory block, got {}", buffer.n_memory()),
+            ));
+        }
+        let memory = buffer.peek_memory(0);
+        let fd = memory
+            .downcast_memory_ref::<gst_allocators::DmaBufMemory>()
+            .map(|dmabuf| dmabuf.fd())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "buffer memory is not a DMA-BUF"))?;
+
+        let (offset, stride) = match buffer.meta::<gstreamer_video::VideoMeta>() {
+            Some(meta) => (meta.offset()[0], meta.stride()[0] as usize),
+            None => (0, layout.width as usize * 4),
+        };
+        let plane = DmaBufPlane { fd, offset: memory.offset() + offset, stride };
+
+        Ok(Self {
+            buffer,
+            plane,
+            width: layout.width,
+            height: layout.height,
+            format: layout.format,
+            modifier: DRM_FORMAT_MOD_LINEAR,
+        })
+    }
+
+    /// Copy the pixels into `out`, tightly packed
+    pub fn read_into(&self, out: &mut Vec<u8>) -> io::Result<()> {
+        read_plane(&self.plane, self.width, self.height, out)
+    }
+}
+
+/// Map a linear plane and copy its `width` x `height` pixels into `out`,
+/// dropping any row padding
+pub fn read_plane(plane: &DmaBufPlane, width: u32, height: u32, out: &mut Vec<u8>) -> io::Result<()> {
+    let row = width as usize * 4;
+    out.clear();
+    if row == 0 || height == 0 {
+        return Ok(());
+    }
+    if plane.stride < row {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("stride {} is shorter than a {} byte row", plane.stride, row),
+        ));
+    }
+
+    let len = plane.offset + plane.stride * (height as usize - 1) + row;
+    let mapping = Mapping::new(plane.fd, len)?;
+    sync(plane.fd, DMA_BUF_SYNC_START | DMA_BUF_SYNC_READ);
+    out.reserve(row * height as usize);
+    for y in 0..height as usize {
+        let start = plane.offset + y * plane.stride;
+        out.extend_from_slice(&mapping.as_slice()[start..start + row]);
+    }
+    sync(plane.fd, DMA_BUF_SYNC_END | DMA_BUF_SYNC_READ);
+    Ok(())
+}
+
+/// Tell the exporter the CPU is about to read or is done reading, so
+/// caches stay coherent
+fn sync(fd: RawFd, flags: u64) {
+    // Fds that aren't DMA-BUFs, like memfds, have nothing to sync and
+    // answer with ENOTTY
+    unsafe {
+        libc::ioctl(fd, DMA_BUF_IOCTL_SYNC as _, &flags as *const u64);
+    }
+}
+
+/// Read-only mapping of a buffer, unmapped on drop
+struct Mapping {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize) -> io::Result<Self> {
+        let addr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, fd, 0) };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { addr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    /// Stands in for a PipeWire stream: settles on DMA-BUFs with
+    /// `modifier` when the compositor has them and they are offered, on
+    /// shared memory otherwise
+    struct MockStream {
+        modifier: Option<u64>,
+    }
+
+    impl MockStream {
+        fn negotiate(&self, negotiation: &DmaBufNegotiation) -> StreamCaps {
+            match self.modifier {
+                Some(modifier) if negotiation.dmabuf_allowed() => StreamCaps {
+                    width: 3840,
+                    height: 2160,
+                    format: "DMA_DRM".to_string(),
+                    drm_format: Some(DrmFormat::Xrgb8888.drm_string(modifier)),
+                    dmabuf: true,
+                },
+                _ => StreamCaps {
+                    width: 3840,
+                    height: 2160,
+                    format: "BGRx".to_string(),
+                    drm_format: None,
+                    dmabuf: false,
+                },
+            }
+        }
+    }
+
+    fn layout(path: BufferPath) -> Negotiated {
+        Negotiated::Ready(StreamLayout { width: 3840, height: 2160, format: DrmFormat::Xrgb8888, path })
+    }
+
+    #[test]
+    fn test_linear_dmabuf_is_used_when_offered() {
+        let stream = MockStream { modifier: Some(DRM_FORMAT_MOD_LINEAR) };
+        let mut negotiation = DmaBufNegotiation::new(true);
+        assert_eq!(negotiation.accept(&stream.negotiate(&negotiation)), layout(BufferPath::DmaBuf));
+        assert!(negotiation.dmabuf_allowed());
+    }
+
+    #[test]
+    fn test_tiled_dmabuf_falls_back_to_shared_memory() {
+        // I915_FORMAT_MOD_X_TILED
+        let stream = MockStream { modifier: Some(0x0100_0000_0000_0001) };
+        let mut negotiation = DmaBufNegotiation::new(true);
+        match negotiation.accept(&stream.negotiate(&negotiation)) {
+            Negotiated::Renegotiate { reason } => assert!(reason.contains("0x100000000000001"), "{}", reason),
+            other => panic!("expected renegotiation, got {:?}", other),
+        }
+        assert!(!negotiation.dmabuf_allowed());
+        assert_eq!(negotiation.accept(&stream.negotiate(&negotiation)), layout(BufferPath::Shm));
+    }
+
+    #[test]
+    fn test_compositors_without_dmabuf_use_shared_memory() {
+        let stream = MockStream { modifier: None };
+        let mut negotiation = DmaBufNegotiation::new(true);
+        assert_eq!(negotiation.accept(&stream.negotiate(&negotiation)), layout(BufferPath::Shm));
+
+        // Turned off by configuration
+        let stream = MockStream { modifier: Some(DRM_FORMAT_MOD_LINEAR) };
+        let mut negotiation = DmaBufNegotiation::new(false);
+        assert_eq!(negotiation.accept(&stream.negotiate(&negotiation)), layout(BufferPath::Shm));
+    }
+
+    #[test]
+    fn test_failed_import_stays_on_shared_memory() {
+        let stream = MockStream { modifier: Some(DRM_FORMAT_MOD_LINEAR) };
+        let mut negotiation = DmaBufNegotiation::new(true);
+        assert_eq!(negotiation.accept(&stream.negotiate(&negotiation)), layout(BufferPath::DmaBuf));
+        negotiation.import_failed();
+        assert_eq!(negotiation.accept(&stream.negotiate(&negotiation)), layout(BufferPath::Shm));
+    }
+
+    #[test]
+    fn test_caps_formats() {
+        assert_eq!(DrmFormat::Xrgb8888.drm_string(DRM_FORMAT_MOD_LINEAR), "XR24");
+        assert_eq!(DrmFormat::Abgr8888.drm_string(0x0100_0000_0000_0002), "AB24:0x0100000000000002");
+        assert_eq!(DrmFormat::parse_drm_string("XR24"), Some((DrmFormat::Xrgb8888, DRM_FORMAT_MOD_LINEAR)));
+        assert_eq!(
+            DrmFormat::parse_drm_string("AB24:0x0100000000000002"),
+            Some((DrmFormat::Abgr8888, 0x0100_0000_0000_0002))
+        );
+        assert_eq!(DrmFormat::parse_drm_string("NV12"), None);
+        assert_eq!(DrmFormat::parse_drm_string("XR24:tiled"), None);
+
+        // Pre-1.24 DMA-BUF caps carry the video format and are linear
+        let mut negotiation = DmaBufNegotiation::new(true);
+        let caps = StreamCaps {
+            width: 3840,
+            height: 2160,
+            format: "BGRx".to_string(),
+            drm_format: None,
+            dmabuf: true,
+        };
+        assert_eq!(negotiation.accept(&caps), layout(BufferPath::DmaBuf));
+    }
+
+    #[test]
+    fn test_read_plane_drops_row_padding() {
+        // A memfd maps like a linear DMA-BUF
+        let fd = unsafe { libc::memfd_create(c"ghostlink-dmabuf-test".as_ptr(), 0) };
+        assert!(fd >= 0);
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // 2x2 pixels, 12 byte rows, after a 4 byte header
+        let mut contents = vec![0xeeu8; 4];
+        contents.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0]);
+        contents.extend_from_slice(&[9, 10, 11, 12, 13, 14, 15, 16]);
+        let written = unsafe { libc::write(fd.as_raw_fd(), contents.as_ptr().cast(), contents.len()) };
+        assert_eq!(written as usize, contents.len());
+
+        let plane = DmaBufPlane { fd: fd.as_raw_fd(), offset: 4, stride: 12 };
+        let mut out = Vec::new();
+        read_plane(&plane, 2, 2, &mut out).unwrap();
+        assert_eq!(out, (1..=16).collect::<Vec<u8>>());
+
+        let narrow = DmaBufPlane { stride: 4, ..plane };
+        assert!(read_plane(&narrow, 2, 2, &mut out).is_err());
+    }
+}
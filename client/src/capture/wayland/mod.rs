@@ -14,6 +14,7 @@
 #![allow(dead_code)]
 
 pub mod portal;
+pub mod dmabuf;
 pub mod pipewire;
 pub mod capturer;
 
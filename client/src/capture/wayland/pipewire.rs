@@ -1,19 +1,27 @@
 //! PipeWire stream capture using GStreamer
 //!
 //! Creates a GStreamer pipeline to capture frames from PipeWire streams
-//! obtained via the XDG Desktop Portal.
+//! obtained via the XDG Desktop Portal. DMA-BUFs are offered first and
+//! shared memory is the fallback; see [`super::dmabuf`].
 
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use gstreamer as gst;
 use gstreamer::prelude::*;
 use gstreamer_app::AppSink;
 
-use tracing::{debug, trace, warn};
+use tracing::{debug, info, trace, warn};
 
+use crate::capture::BufferPath;
 use crate::error::{Result, GhostLinkError, CaptureError};
+use super::dmabuf::{
+    DmaBufFrame, DmaBufNegotiation, DrmFormat, Negotiated, StreamCaps, StreamLayout, DRM_FORMAT_MOD_LINEAR,
+};
 use super::portal::StreamInfo;
 
+/// GStreamer caps feature for DMA-BUF backed buffers
+const CAPS_FEATURE_DMABUF: &str = "memory:DMABuf";
+
 /// Pixel format from PipeWire/GStreamer
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PipeWirePixelFormat {
@@ -59,9 +67,15 @@ impl From<&StreamInfo> for PipeWireStream {
 pub struct PipeWireRecorder {
     pipeline: gst::Pipeline,
     appsink: AppSink,
+    /// Portal's PipeWire remote, kept open by the portal session
+    fd: RawFd,
+    stream_path: u64,
     width: u32,
     height: u32,
     pixel_format: PipeWirePixelFormat,
+    negotiation: DmaBufNegotiation,
+    /// Layout the stream settled on, known from the first frame
+    layout: Option<StreamLayout>,
     // Cached buffer for frame comparison (skip unchanged frames)
     last_frame_hash: u64,
 }
@@ -72,6 +86,28 @@ impl PipeWireRecorder {
         // Ensure GStreamer is initialized
         super::init_gstreamer()?;
 
+        let dmabuf = std::env::var("GHOSTLINK_DMABUF")
+            .map(|v| v == "1" || v.to_lowercase() == "true")
+            .unwrap_or(true);
+        let negotiation = DmaBufNegotiation::new(dmabuf);
+        let (pipeline, appsink) = Self::start_pipeline(fd.as_raw_fd(), stream.path, negotiation.dmabuf_allowed())?;
+
+        Ok(Self {
+            pipeline,
+            appsink,
+            fd: fd.as_raw_fd(),
+            stream_path: stream.path,
+            width: stream.size.0,
+            height: stream.size.1,
+            pixel_format: PipeWirePixelFormat::BGRx, // Will be updated on first frame
+            negotiation,
+            layout: None,
+            last_frame_hash: 0,
+        })
+    }
+
+    /// Build and start the pipeline, offering DMA-BUFs when `dmabuf` is set
+    fn start_pipeline(fd: RawFd, path: u64, dmabuf: bool) -> Result<(gst::Pipeline, AppSink)> {
         let pipeline = gst::Pipeline::new();
 
         // Create pipewiresrc element
@@ -82,12 +118,14 @@ impl PipeWireRecorder {
             }))?;
 
         // Configure pipewiresrc
-        src.set_property("fd", fd.as_raw_fd());
-        src.set_property("path", format!("{}", stream.path));
+        src.set_property("fd", fd);
+        src.set_property("path", format!("{}", path));
         src.set_property("keepalive-time", 1000i32);
         // Critical: set always-copy to avoid PipeWire destruction deadlock
         // See: https://gitlab.freedesktop.org/pipewire/pipewire/-/issues/982
-        src.set_property("always-copy", true);
+        // Copying would defeat DMA-BUFs, so that path instead never holds a
+        // buffer past the frame it belongs to
+        src.set_property("always-copy", !dmabuf);
 
         // Create appsink for frame extraction
         let sink = gst::ElementFactory::make("appsink")
@@ -120,14 +158,10 @@ impl PipeWireRecorder {
             })
         })?;
 
-        // Set accepted caps (BGRx or RGBx for efficiency)
-        let caps = gst::Caps::builder("video/x-raw")
-            .field("format", gst::List::new(["BGRx", "RGBx", "BGRA", "RGBA"]))
-            .build();
-        appsink.set_caps(Some(&caps));
+        appsink.set_caps(Some(&appsink_caps(dmabuf)));
 
         // Start the pipeline
-        debug!("Starting GStreamer pipeline for stream {}", stream.path);
+        debug!("Starting GStreamer pipeline for stream {} (DMA-BUF {})", path, if dmabuf { "offered" } else { "off" });
         pipeline.set_state(gst::State::Playing).map_err(|e| {
             GhostLinkError::Capture(CaptureError::InitializationFailed {
                 reason: format!("Failed to start pipeline: {}", e),
@@ -148,14 +182,19 @@ impl PipeWireRecorder {
         // Small delay to let things settle (empirically helps stability)
         std::thread::sleep(std::time::Duration::from_millis(100));
 
-        Ok(Self {
-            pipeline,
-            appsink,
-            width: stream.size.0,
-            height: stream.size.1,
-            pixel_format: PipeWirePixelFormat::BGRx, // Will be updated on first frame
-            last_frame_hash: 0,
-        })
+        Ok((pipeline, appsink))
+    }
+
+    /// Restart the stream on shared memory after DMA-BUFs didn't work out
+    fn fall_back_to_shm(&mut self, reason: &str) -> Result<()> {
+        warn!("DMA-BUF capture of stream {} unusable ({}), falling back to shared memory", self.stream_path, reason);
+        self.negotiation.import_failed();
+        self.layout = None;
+        stop_pipeline(&self.pipeline);
+        let (pipeline, appsink) = Self::start_pipeline(self.fd, self.stream_path, false)?;
+        self.pipeline = pipeline;
+        self.appsink = appsink;
+        Ok(())
     }
 
     /// Capture a frame from the PipeWire stream
@@ -174,44 +213,25 @@ impl PipeWireRecorder {
             })
         })?;
 
-        let structure = caps.structure(0).ok_or_else(|| {
-            GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
-                reason: "No structure in caps".into(),
-            })
-        })?;
-
-        // Extract width and height
-        let width: i32 = structure.get("width").map_err(|e| {
-            GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
-                reason: format!("Failed to get width: {}", e),
-            })
-        })?;
-        let height: i32 = structure.get("height").map_err(|e| {
-            GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
-                reason: format!("Failed to get height: {}", e),
-            })
-        })?;
-
-        self.width = width as u32;
-        self.height = height as u32;
-
-        // Extract pixel format
-        let format_str: String = structure.get("format").map_err(|e| {
-            GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
-                reason: format!("Failed to get format: {}", e),
-            })
-        })?;
-
-        self.pixel_format = match format_str.as_str() {
-            "BGRx" => PipeWirePixelFormat::BGRx,
-            "RGBx" => PipeWirePixelFormat::RGBx,
-            "BGRA" => PipeWirePixelFormat::BGRA,
-            "RGBA" => PipeWirePixelFormat::RGBA,
-            other => {
-                warn!("Unknown pixel format: {}, assuming BGRx", other);
-                PipeWirePixelFormat::BGRx
+        let layout = match self.negotiation.accept(&stream_caps(caps)?) {
+            Negotiated::Ready(layout) => layout,
+            Negotiated::Renegotiate { reason } => {
+                drop(sample);
+                self.fall_back_to_shm(&reason)?;
+                return Ok(None);
             }
         };
+        if self.layout != Some(layout) {
+            info!(
+                "PipeWire stream {} delivers {}x{} {:?} over {:?}",
+                self.stream_path, layout.width, layout.height, layout.format, layout.path
+            );
+            self.layout = Some(layout);
+        }
+
+        self.width = layout.width;
+        self.height = layout.height;
+        self.pixel_format = layout.format.pixel_format();
 
         // Get the buffer
         let buffer = sample.buffer_owned().ok_or_else(|| {
@@ -219,11 +239,23 @@ impl PipeWireRecorder {
                 reason: "No buffer in sample".into(),
             })
         })?;
+        drop(sample);
 
         // Check for crop metadata (for window captures)
         let crop = buffer
             .meta::<gstreamer_video::VideoCropMeta>()
-            .map(|m| m.rect());
+            .map(|m| m.rect())
+            .filter(|&(x, y, w, h)| !(x == 0 && y == 0 && w == self.width && h == self.height));
+
+        if layout.path == BufferPath::DmaBuf {
+            return match self.read_dmabuf(buffer, &layout, crop) {
+                Ok(frame) => Ok(frame),
+                Err(e) => {
+                    self.fall_back_to_shm(&e.to_string())?;
+                    Ok(None)
+                }
+            };
+        }
 
         // Map buffer for reading
         let mapped = buffer.into_mapped_buffer_readable().map_err(|_| {
@@ -234,14 +266,6 @@ impl PipeWireRecorder {
 
         let data = mapped.as_slice();
 
-        // Quick hash check to skip unchanged frames
-        let frame_hash = Self::hash_frame(data);
-        if frame_hash == self.last_frame_hash {
-            trace!("Frame unchanged, skipping");
-            return Ok(None);
-        }
-        self.last_frame_hash = frame_hash;
-
         // Validate buffer size
         let expected_size = (self.width * self.height * 4) as usize;
         if data.len() != expected_size {
@@ -254,35 +278,76 @@ impl PipeWireRecorder {
 
         // Handle crop if present
         let (final_data, final_width, final_height) = if let Some((x, y, w, h)) = crop {
-            if x == 0 && y == 0 && w == self.width && h == self.height {
-                // No actual cropping needed
-                (data.to_vec(), self.width, self.height)
-            } else {
-                // Need to extract cropped region
-                let cropped = Self::extract_crop(
-                    data,
-                    self.width as usize,
-                    x as usize,
-                    y as usize,
-                    w as usize,
-                    h as usize,
-                );
-                (cropped, w, h)
-            }
+            // Need to extract cropped region
+            let cropped = Self::extract_crop(
+                data,
+                self.width as usize,
+                x as usize,
+                y as usize,
+                w as usize,
+                h as usize,
+            );
+            (cropped, w, h)
         } else {
             (data.to_vec(), self.width, self.height)
         };
 
-        Ok(Some(CapturedFrame {
-            data: final_data,
-            width: final_width,
-            height: final_height,
+        Ok(self.unless_unchanged(final_data, final_width, final_height, None))
+    }
+
+    /// Map a DMA-BUF once into system memory; the buffer itself goes along
+    /// for encoders that import it, unless the frame is cropped
+    fn read_dmabuf(
+        &mut self,
+        buffer: gst::Buffer,
+        layout: &StreamLayout,
+        crop: Option<(u32, u32, u32, u32)>,
+    ) -> std::io::Result<Option<CapturedFrame>> {
+        let dmabuf = DmaBufFrame::from_buffer(buffer, layout)?;
+        let mut data = Vec::new();
+        dmabuf.read_into(&mut data)?;
+
+        Ok(match crop {
+            Some((x, y, w, h)) => {
+                let cropped = Self::extract_crop(&data, self.width as usize, x as usize, y as usize, w as usize, h as usize);
+                self.unless_unchanged(cropped, w, h, None)
+            }
+            None => self.unless_unchanged(data, self.width, self.height, Some(dmabuf)),
+        })
+    }
+
+    /// Wrap the frame, or skip it when it hashes like the last one
+    fn unless_unchanged(
+        &mut self,
+        data: Vec<u8>,
+        width: u32,
+        height: u32,
+        dmabuf: Option<DmaBufFrame>,
+    ) -> Option<CapturedFrame> {
+        // Quick hash check to skip unchanged frames
+        let frame_hash = Self::hash_frame(&data);
+        if frame_hash == self.last_frame_hash {
+            trace!("Frame unchanged, skipping");
+            return None;
+        }
+        self.last_frame_hash = frame_hash;
+
+        Some(CapturedFrame {
+            data,
+            width,
+            height,
             pixel_format: self.pixel_format,
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_millis() as u64,
-        }))
+            dmabuf,
+        })
+    }
+
+    /// How frames reach us, once the stream has negotiated
+    pub fn buffer_path(&self) -> BufferPath {
+        self.layout.map_or(BufferPath::Shm, |layout| layout.path)
     }
 
     /// Get current resolution
@@ -331,13 +396,81 @@ impl PipeWireRecorder {
 
 impl Drop for PipeWireRecorder {
     fn drop(&mut self) {
-        debug!("Stopping PipeWire recorder pipeline");
-        if let Err(e) = self.pipeline.set_state(gst::State::Null) {
-            warn!("Failed to stop pipeline: {}", e);
+        stop_pipeline(&self.pipeline);
+    }
+}
+
+fn stop_pipeline(pipeline: &gst::Pipeline) {
+    debug!("Stopping PipeWire recorder pipeline");
+    if let Err(e) = pipeline.set_state(gst::State::Null) {
+        warn!("Failed to stop pipeline: {}", e);
+    }
+    // Wait for state change to complete
+    let _ = pipeline.state(gst::ClockTime::from_mseconds(2000));
+}
+
+/// Caps the appsink accepts: linear DMA-BUFs first when offered, in both
+/// the GStreamer 1.24 `DMA_DRM` form and the older one, then system memory
+fn appsink_caps(dmabuf: bool) -> gst::Caps {
+    let formats = DrmFormat::ALL.map(DrmFormat::video_format);
+    let mut caps = gst::Caps::new_empty();
+    {
+        let caps = caps.get_mut().expect("new caps are writable");
+        if dmabuf {
+            caps.append(
+                gst::Caps::builder("video/x-raw")
+                    .features([CAPS_FEATURE_DMABUF])
+                    .field("format", "DMA_DRM")
+                    .field("drm-format", gst::List::new(DrmFormat::ALL.map(|format| format.drm_string(DRM_FORMAT_MOD_LINEAR))))
+                    .build(),
+            );
+            caps.append(
+                gst::Caps::builder("video/x-raw")
+                    .features([CAPS_FEATURE_DMABUF])
+                    .field("format", gst::List::new(formats))
+                    .build(),
+            );
         }
-        // Wait for state change to complete
-        let _ = self.pipeline.state(gst::ClockTime::from_mseconds(2000));
+        caps.append(
+            gst::Caps::builder("video/x-raw")
+                .field("format", gst::List::new(formats))
+                .build(),
+        );
     }
+    caps
+}
+
+/// Read what the stream negotiated off a sample's caps
+fn stream_caps(caps: &gst::CapsRef) -> Result<StreamCaps> {
+    let structure = caps.structure(0).ok_or_else(|| {
+        GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
+            reason: "No structure in caps".into(),
+        })
+    })?;
+
+    let field = |name: &str| {
+        structure.get::<i32>(name).map_err(|e| {
+            GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
+                reason: format!("Failed to get {}: {}", name, e),
+            })
+        })
+    };
+    let width = field("width")?;
+    let height = field("height")?;
+
+    let format: String = structure.get("format").map_err(|e| {
+        GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
+            reason: format!("Failed to get format: {}", e),
+        })
+    })?;
+
+    Ok(StreamCaps {
+        width: width as u32,
+        height: height as u32,
+        format,
+        drm_format: structure.get("drm-format").ok(),
+        dmabuf: caps.features(0).is_some_and(|features| features.contains(CAPS_FEATURE_DMABUF)),
+    })
 }
 
 /// A captured frame from PipeWire
@@ -348,13 +481,15 @@ pub struct CapturedFrame {
     pub height: u32,
     pub pixel_format: PipeWirePixelFormat,
     pub timestamp: u64,
+    /// The DMA-BUF the pixels were mapped from, for encoders that import it
+    pub dmabuf: Option<DmaBufFrame>,
 }
 
 impl CapturedFrame {
-    /// Convert to the generic Frame type
-    pub fn to_frame(&self) -> crate::capture::Frame {
+    /// Convert to the generic Frame type, handing over the pixels
+    pub fn into_frame(self) -> crate::capture::Frame {
         crate::capture::Frame {
-            data: self.data.clone(),
+            data: self.data,
             width: self.width,
             height: self.height,
             stride: self.width * 4,
@@ -2,11 +2,16 @@
 //!
 //! Uses DBus to communicate with org.freedesktop.portal.ScreenCast
 //! to request screen capture permissions and obtain PipeWire stream info.
+//!
+//! Where the portal offers org.freedesktop.portal.RemoteDesktop, the capture
+//! session is created as a remote desktop session instead, so the user is
+//! asked once for both screen and input access. The session is then shared
+//! with input injection through [`shared_session`].
 
 use std::collections::HashMap;
 use std::os::unix::io::{OwnedFd, FromRawFd};
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::time::Duration;
 
 use dbus::arg::{PropMap, RefArg, Variant};
@@ -16,6 +21,14 @@ use dbus::Message;
 
 use tracing::{debug, info, warn};
 
+const PORTAL_BUS: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const SCREENCAST_IFACE: &str = "org.freedesktop.portal.ScreenCast";
+const REMOTE_DESKTOP_IFACE: &str = "org.freedesktop.portal.RemoteDesktop";
+
+/// Keyboard and pointer, the devices requested for a remote desktop session
+const REMOTE_DESKTOP_DEVICES: u32 = 1 | 2;
+
 use crate::error::{Result, GhostLinkError, CaptureError};
 
 /// Portal session information
@@ -25,6 +38,9 @@ pub struct PortalSession {
     pub streams: Vec<StreamInfo>,
     pub fd: OwnedFd,
     pub supports_restore_token: bool,
+    /// RemoteDesktop device types granted (keyboard = 1, pointer = 2,
+    /// touchscreen = 4); zero for a screencast-only session
+    pub devices: u32,
 }
 
 impl std::fmt::Debug for PortalSession {
@@ -33,6 +49,7 @@ impl std::fmt::Debug for PortalSession {
             .field("session_path", &self.session_path)
             .field("streams", &self.streams)
             .field("supports_restore_token", &self.supports_restore_token)
+            .field("devices", &self.devices)
             .finish()
     }
 }
@@ -81,10 +98,107 @@ impl dbus::arg::ReadAll for PortalResponse {
     }
 }
 
+/// The capturer's portal session, as seen by input injection.
+///
+/// Portal sessions belong to the DBus connection that created them, so input
+/// has to be injected over the capturer's connection rather than a new one.
+#[derive(Clone)]
+pub struct SharedPortalSession {
+    pub conn: Arc<SyncConnection>,
+    pub session_path: dbus::Path<'static>,
+    pub streams: Vec<StreamInfo>,
+    pub devices: u32,
+    /// Index into `streams` of the display being captured
+    pub selected_stream: usize,
+}
+
+impl std::fmt::Debug for SharedPortalSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedPortalSession")
+            .field("session_path", &self.session_path)
+            .field("streams", &self.streams)
+            .field("devices", &self.devices)
+            .field("selected_stream", &self.selected_stream)
+            .finish()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SHARED_SESSION: Mutex<Option<SharedPortalSession>> = Mutex::new(None);
+}
+
+/// Make a capture session available to input injection
+pub fn publish_session(session: &PortalSession) {
+    *SHARED_SESSION.lock().unwrap() = Some(SharedPortalSession {
+        conn: session.conn.clone(),
+        session_path: session.session_path.clone(),
+        streams: session.streams.clone(),
+        devices: session.devices,
+        selected_stream: 0,
+    });
+}
+
+/// The active capture session, if any
+pub fn shared_session() -> Option<SharedPortalSession> {
+    SHARED_SESSION.lock().unwrap().clone()
+}
+
+/// Record which stream is being captured, so pointer input lands on it
+pub fn select_shared_stream(index: usize) {
+    if let Some(session) = SHARED_SESSION.lock().unwrap().as_mut() {
+        session.selected_stream = index;
+    }
+}
+
+/// Withdraw a capture session that is being closed
+pub fn clear_shared_session(session_path: &dbus::Path<'static>) {
+    let mut shared = SHARED_SESSION.lock().unwrap();
+    if shared.as_ref().is_some_and(|s| &s.session_path == session_path) {
+        *shared = None;
+    }
+}
+
+/// State filled in as the portal answers each request of the handshake
+#[derive(Clone, Default)]
+struct PendingSession {
+    fd: Arc<Mutex<Option<OwnedFd>>>,
+    streams: Arc<Mutex<Vec<StreamInfo>>>,
+    session_path: Arc<Mutex<Option<dbus::Path<'static>>>>,
+    failure: Arc<AtomicBool>,
+    /// The user turned down the share dialog
+    denied: Arc<AtomicBool>,
+    restore_token: Arc<Mutex<Option<String>>>,
+    devices: Arc<AtomicU32>,
+}
+
+/// How the session is requested
+#[derive(Clone)]
+struct SessionRequest {
+    /// Create a RemoteDesktop session (screen and input) rather than a
+    /// ScreenCast one
+    remote_desktop: bool,
+    capture_cursor: bool,
+    restore_token: Option<String>,
+    supports_restore: bool,
+}
+
+impl SessionRequest {
+    /// Interface that owns the session and its Start call
+    fn interface(&self) -> &'static str {
+        if self.remote_desktop {
+            REMOTE_DESKTOP_IFACE
+        } else {
+            SCREENCAST_IFACE
+        }
+    }
+}
+
 /// ScreenCast portal interface
 pub struct ScreenCastPortal {
-    conn: SyncConnection,
+    conn: Arc<SyncConnection>,
     portal_version: u32,
+    /// RemoteDesktop interface version, if the portal backend has one
+    remote_desktop_version: Option<u32>,
 }
 
 impl ScreenCastPortal {
@@ -96,16 +210,9 @@ impl ScreenCastPortal {
             })
         })?;
 
-        let portal = Self::get_portal_proxy(&conn);
-
         // Get portal version to check feature support
-        // Use introspection to get version property
-        let version: u32 = match portal.method_call::<(dbus::arg::Variant<u32>,), _, _, _>(
-            "org.freedesktop.DBus.Properties",
-            "Get",
-            ("org.freedesktop.portal.ScreenCast", "version"),
-        ) {
-            Ok((v,)) => v.0,
+        let version = match Self::interface_version(&conn, SCREENCAST_IFACE) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Failed to get portal version: {}, assuming v4", e);
                 4 // Assume version 4 (restore token support)
@@ -114,16 +221,39 @@ impl ScreenCastPortal {
 
         info!("ScreenCast portal version: {}", version);
 
-        Ok(Self { conn, portal_version: version })
+        // Backends without input support (e.g. xdg-desktop-portal-wlr) have no
+        // RemoteDesktop interface at all
+        let remote_desktop_version = match Self::interface_version(&conn, REMOTE_DESKTOP_IFACE) {
+            Ok(v) => {
+                info!("RemoteDesktop portal version: {}", v);
+                Some(v)
+            }
+            Err(e) => {
+                info!("RemoteDesktop portal not available: {}", e);
+                None
+            }
+        };
+
+        Ok(Self {
+            conn: Arc::new(conn),
+            portal_version: version,
+            remote_desktop_version,
+        })
     }
 
     /// Get a proxy to the portal
     fn get_portal_proxy(conn: &SyncConnection) -> Proxy<&SyncConnection> {
-        conn.with_proxy(
-            "org.freedesktop.portal.Desktop",
-            "/org/freedesktop/portal/desktop",
-            Duration::from_millis(5000),
-        )
+        conn.with_proxy(PORTAL_BUS, PORTAL_PATH, Duration::from_millis(5000))
+    }
+
+    /// Read the `version` property of a portal interface
+    fn interface_version(conn: &SyncConnection, interface: &str) -> std::result::Result<u32, dbus::Error> {
+        let (version,): (Variant<u32>,) = Self::get_portal_proxy(conn).method_call(
+            "org.freedesktop.DBus.Properties",
+            "Get",
+            (interface, "version"),
+        )?;
+        Ok(version.0)
     }
 
     /// Check if restore tokens are supported (version >= 4)
@@ -131,6 +261,11 @@ impl ScreenCastPortal {
         self.portal_version >= 4
     }
 
+    /// Check if the portal can grant input injection alongside capture
+    pub fn supports_remote_desktop(&self) -> bool {
+        self.remote_desktop_version.is_some()
+    }
+
     /// Request screen capture access
     ///
     /// This will show a permission dialog to the user if needed.
@@ -140,14 +275,37 @@ impl ScreenCastPortal {
         capture_cursor: bool,
         restore_token: Option<&str>,
     ) -> Result<PortalSession> {
+        self.request_session(SessionRequest {
+            remote_desktop: false,
+            capture_cursor,
+            restore_token: restore_token.map(str::to_string),
+            supports_restore: self.supports_restore_token(),
+        })
+    }
+
+    /// Request screen capture and keyboard/pointer access in one dialog
+    ///
+    /// The returned session's `devices` holds the input devices the user
+    /// granted.
+    pub fn request_remote_desktop(
+        &self,
+        capture_cursor: bool,
+        restore_token: Option<&str>,
+    ) -> Result<PortalSession> {
+        self.request_session(SessionRequest {
+            remote_desktop: true,
+            capture_cursor,
+            restore_token: restore_token.map(str::to_string),
+            // RemoteDesktop sessions persist from interface version 2
+            supports_restore: self.remote_desktop_version.unwrap_or(0) >= 2,
+        })
+    }
+
+    fn request_session(&self, request: SessionRequest) -> Result<PortalSession> {
         let portal = Self::get_portal_proxy(&self.conn);
 
         // Shared state for async response handling
-        let fd: Arc<Mutex<Option<OwnedFd>>> = Arc::new(Mutex::new(None));
-        let streams: Arc<Mutex<Vec<StreamInfo>>> = Arc::new(Mutex::new(Vec::new()));
-        let session_path: Arc<Mutex<Option<dbus::Path<'static>>>> = Arc::new(Mutex::new(None));
-        let failure: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-        let new_restore_token: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let pending = PendingSession::default();
 
         // Step 1: Create session
         let mut args: PropMap = HashMap::new();
@@ -155,7 +313,7 @@ impl ScreenCastPortal {
         args.insert("handle_token".into(), Variant(Box::new("ghostlink_handle".to_string())));
 
         let (create_result,): (dbus::Path<'static>,) = portal.method_call(
-            "org.freedesktop.portal.ScreenCast",
+            request.interface(),
             "CreateSession",
             (args,),
         ).map_err(|e| {
@@ -167,14 +325,8 @@ impl ScreenCastPortal {
         debug!("CreateSession request path: {}", create_result);
 
         // Set up response handler for CreateSession
-        let fd_clone = fd.clone();
-        let streams_clone = streams.clone();
-        let session_clone = session_path.clone();
-        let failure_clone = failure.clone();
-        let restore_clone = new_restore_token.clone();
-        let capture_cursor_flag = capture_cursor;
-        let has_restore = restore_token.map(|s| s.to_string());
-        let supports_restore = self.supports_restore_token();
+        let pending_clone = pending.clone();
+        let request_clone = request.clone();
 
         self.setup_response_handler(
             create_result.clone(),
@@ -182,17 +334,11 @@ impl ScreenCastPortal {
                 Self::on_create_session_response(
                     response,
                     conn,
-                    fd_clone.clone(),
-                    streams_clone.clone(),
-                    session_clone.clone(),
-                    failure_clone.clone(),
-                    restore_clone.clone(),
-                    capture_cursor_flag,
-                    has_restore.clone(),
-                    supports_restore,
+                    pending_clone.clone(),
+                    request_clone.clone(),
                 )
             },
-            failure.clone(),
+            pending.failure.clone(),
         )?;
 
         // Wait for user interaction (up to 3 minutes)
@@ -204,12 +350,17 @@ impl ScreenCastPortal {
             })?;
 
             // Check if we got the file descriptor
-            if fd.lock().unwrap().is_some() {
+            if pending.fd.lock().unwrap().is_some() {
                 break;
             }
 
             // Check for failure
-            if failure.load(Ordering::SeqCst) {
+            if pending.denied.load(Ordering::SeqCst) {
+                return Err(GhostLinkError::Capture(CaptureError::PermissionDenied {
+                    backend: "Portal".into(),
+                }));
+            }
+            if pending.failure.load(Ordering::SeqCst) {
                 return Err(GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
                     reason: "Portal request failed or was cancelled by user".into(),
                 }));
@@ -217,23 +368,29 @@ impl ScreenCastPortal {
         }
 
         // Extract results
-        let fd_result = fd.lock().unwrap().take();
-        let streams_result = streams.lock().unwrap().clone();
-        let session_result = session_path.lock().unwrap().clone();
+        let fd_result = pending.fd.lock().unwrap().take();
+        let streams_result = pending.streams.lock().unwrap().clone();
+        let session_result = pending.session_path.lock().unwrap().clone();
+        let devices = pending.devices.load(Ordering::SeqCst);
 
         match (fd_result, session_result) {
             (Some(fd), Some(session)) if !streams_result.is_empty() => {
                 info!("Portal session established with {} streams", streams_result.len());
+                if request.remote_desktop {
+                    info!("RemoteDesktop devices granted: keyboard={}, pointer={}",
+                        devices & 1 != 0,
+                        devices & 2 != 0,
+                    );
+                }
                 Ok(PortalSession {
-                    conn: Arc::new(SyncConnection::new_session().map_err(|e| {
-                        GhostLinkError::Capture(CaptureError::InitializationFailed {
-                            reason: format!("Failed to create new session connection: {}", e),
-                        })
-                    })?),
+                    // The session belongs to this connection; it must outlive
+                    // the portal object for input injection to keep working
+                    conn: self.conn.clone(),
                     session_path: session,
                     streams: streams_result,
                     fd,
-                    supports_restore_token: supports_restore,
+                    supports_restore_token: request.supports_restore,
+                    devices,
                 })
             }
             _ => Err(GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
@@ -254,13 +411,7 @@ impl ScreenCastPortal {
     {
         let handler = Arc::new(Mutex::new(handler));
 
-        let mut rule = MatchRule::new();
-        rule.path = Some(path);
-        rule.msg_type = Some(MessageType::Signal);
-        rule.sender = Some("org.freedesktop.portal.Desktop".into());
-        rule.interface = Some("org.freedesktop.portal.Request".into());
-
-        self.conn.add_match(rule, move |response: PortalResponse, conn, msg| {
+        self.conn.add_match(Self::request_rule(path), move |response: PortalResponse, conn, msg| {
             debug!("Portal response: {:?}", response.response);
 
             match response.response {
@@ -292,18 +443,22 @@ impl ScreenCastPortal {
         Ok(())
     }
 
-    /// Handle CreateSession response - proceed to SelectSources
+    /// Match rule for the Response signal of a portal request
+    fn request_rule(path: dbus::Path<'static>) -> MatchRule<'static> {
+        let mut rule = MatchRule::new();
+        rule.path = Some(path);
+        rule.msg_type = Some(MessageType::Signal);
+        rule.sender = Some(PORTAL_BUS.into());
+        rule.interface = Some("org.freedesktop.portal.Request".into());
+        rule
+    }
+
+    /// Handle CreateSession response - proceed to SelectDevices or SelectSources
     fn on_create_session_response(
         response: PortalResponse,
         conn: &SyncConnection,
-        fd: Arc<Mutex<Option<OwnedFd>>>,
-        streams: Arc<Mutex<Vec<StreamInfo>>>,
-        session_path: Arc<Mutex<Option<dbus::Path<'static>>>>,
-        failure: Arc<AtomicBool>,
-        restore_token: Arc<Mutex<Option<String>>>,
-        capture_cursor: bool,
-        existing_restore_token: Option<String>,
-        supports_restore: bool,
+        pending: PendingSession,
+        request: SessionRequest,
     ) -> Result<()> {
         // Extract session handle from response
         let session_handle: String = response.results
@@ -315,14 +470,81 @@ impl ScreenCastPortal {
             }))?;
 
         debug!("Session created: {}", session_handle);
-        *session_path.lock().unwrap() = Some(dbus::Path::from(session_handle.clone()));
+        *pending.session_path.lock().unwrap() = Some(dbus::Path::from(session_handle.clone()));
+
+        if request.remote_desktop {
+            Self::select_devices(conn, session_handle, pending, request)
+        } else {
+            Self::select_sources(conn, session_handle, pending, request)
+        }
+    }
+
+    /// Step 2 of a remote desktop session: ask for keyboard and pointer
+    fn select_devices(
+        conn: &SyncConnection,
+        session_handle: String,
+        pending: PendingSession,
+        request: SessionRequest,
+    ) -> Result<()> {
+        let portal = Self::get_portal_proxy(conn);
+
+        let mut args: PropMap = HashMap::new();
+        args.insert("handle_token".into(), Variant(Box::new("ghostlink_devices".to_string())));
+        args.insert("types".into(), Variant(Box::new(REMOTE_DESKTOP_DEVICES)));
+
+        // For a remote desktop session the restore token covers the whole
+        // session and is passed here rather than to SelectSources
+        if request.supports_restore {
+            if let Some(token) = &request.restore_token {
+                args.insert("restore_token".into(), Variant(Box::new(token.clone())));
+            }
+            args.insert("persist_mode".into(), Variant(Box::new(2u32))); // Persist until revoked
+        }
+
+        let (devices_result,): (dbus::Path<'static>,) = portal.method_call(
+            REMOTE_DESKTOP_IFACE,
+            "SelectDevices",
+            (dbus::Path::from(session_handle.clone()), args),
+        ).map_err(|e| {
+            GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
+                reason: format!("SelectDevices failed: {}", e),
+            })
+        })?;
+
+        debug!("SelectDevices request path: {}", devices_result);
+
+        conn.add_match(Self::request_rule(devices_result), move |response: PortalResponse, conn, _msg| {
+            if response.response != 0 {
+                warn!("SelectDevices failed with code: {}", response.response);
+                pending.failure.store(true, Ordering::SeqCst);
+                return true;
+            }
+
+            debug!("SelectDevices succeeded, selecting sources");
+
+            if let Err(e) = Self::select_sources(conn, session_handle.clone(), pending.clone(), request.clone()) {
+                warn!("{}", e);
+                pending.failure.store(true, Ordering::SeqCst);
+            }
+
+            true
+        }).map_err(|e| {
+            GhostLinkError::Capture(CaptureError::FrameCaptureFailed {
+                reason: format!("Failed to add SelectDevices match: {}", e),
+            })
+        })?;
+
+        Ok(())
+    }
 
-        // Step 2: SelectSources
-        let portal = conn.with_proxy(
-            "org.freedesktop.portal.Desktop",
-            "/org/freedesktop/portal/desktop",
-            Duration::from_millis(5000),
-        );
+    /// Choose the monitors to capture
+    fn select_sources(
+        conn: &SyncConnection,
+        session_handle: String,
+        pending: PendingSession,
+        request: SessionRequest,
+    ) -> Result<()> {
+        let portal = Self::get_portal_proxy(conn);
 
         let mut args: PropMap = HashMap::new();
         args.insert("handle_token".into(), Variant(Box::new("ghostlink_sources".to_string())));
@@ -331,22 +553,22 @@ impl ScreenCastPortal {
         args.insert("multiple".into(), Variant(Box::new(true))); // Allow multiple monitors
 
         // Cursor mode: 1=hidden, 2=embedded, 4=metadata
-        if capture_cursor {
+        if request.capture_cursor {
             args.insert("cursor_mode".into(), Variant(Box::new(2u32))); // Embedded
         } else {
             args.insert("cursor_mode".into(), Variant(Box::new(1u32))); // Hidden
         }
 
         // Use restore token if available
-        if supports_restore {
-            if let Some(token) = existing_restore_token {
-                args.insert("restore_token".into(), Variant(Box::new(token)));
+        if request.supports_restore && !request.remote_desktop {
+            if let Some(token) = &request.restore_token {
+                args.insert("restore_token".into(), Variant(Box::new(token.clone())));
             }
             args.insert("persist_mode".into(), Variant(Box::new(2u32))); // Persist until revoked
         }
 
         let (select_result,): (dbus::Path,) = portal.method_call(
-            "org.freedesktop.portal.ScreenCast",
+            SCREENCAST_IFACE,
             "SelectSources",
             (dbus::Path::from(session_handle.clone()), args),
         ).map_err(|e| {
@@ -358,18 +580,7 @@ impl ScreenCastPortal {
         debug!("SelectSources request path: {}", select_result);
 
         // Set up handler for SelectSources response
-        let session_handle_clone = session_handle.clone();
-        Self::setup_select_sources_handler(
-            conn,
-            select_result,
-            session_handle_clone,
-            fd,
-            streams,
-            failure,
-            restore_token,
-        )?;
-
-        Ok(())
+        Self::setup_select_sources_handler(conn, select_result, session_handle, pending, request.interface())
     }
 
     /// Set up handler for SelectSources response - proceed to Start
@@ -377,38 +588,26 @@ impl ScreenCastPortal {
         conn: &SyncConnection,
         path: dbus::Path<'static>,
         session_handle: String,
-        fd: Arc<Mutex<Option<OwnedFd>>>,
-        streams: Arc<Mutex<Vec<StreamInfo>>>,
-        failure: Arc<AtomicBool>,
-        restore_token: Arc<Mutex<Option<String>>>,
+        pending: PendingSession,
+        start_interface: &'static str,
     ) -> Result<()> {
-        let mut rule = MatchRule::new();
-        rule.path = Some(path);
-        rule.msg_type = Some(MessageType::Signal);
-        rule.sender = Some("org.freedesktop.portal.Desktop".into());
-        rule.interface = Some("org.freedesktop.portal.Request".into());
-
-        conn.add_match(rule, move |response: PortalResponse, conn, _msg| {
+        conn.add_match(Self::request_rule(path), move |response: PortalResponse, conn, _msg| {
             if response.response != 0 {
                 warn!("SelectSources failed with code: {}", response.response);
-                failure.store(true, Ordering::SeqCst);
+                pending.failure.store(true, Ordering::SeqCst);
                 return true;
             }
 
             debug!("SelectSources succeeded, calling Start");
 
-            // Step 3: Start the stream
-            let portal = conn.with_proxy(
-                "org.freedesktop.portal.Desktop",
-                "/org/freedesktop/portal/desktop",
-                Duration::from_millis(5000),
-            );
+            // Step 3: Start the session; this is where the dialog is shown
+            let portal = Self::get_portal_proxy(conn);
 
             let mut args: PropMap = HashMap::new();
             args.insert("handle_token".into(), Variant(Box::new("ghostlink_start".to_string())));
 
             let start_result: std::result::Result<(dbus::Path,), dbus::Error> = portal.method_call(
-                "org.freedesktop.portal.ScreenCast",
+                start_interface,
                 "Start",
                 (dbus::Path::from(session_handle.clone()), "", args),
             );
@@ -420,15 +619,12 @@ impl ScreenCastPortal {
                         conn,
                         start_path,
                         session_handle.clone(),
-                        fd.clone(),
-                        streams.clone(),
-                        failure.clone(),
-                        restore_token.clone(),
+                        pending.clone(),
                     );
                 }
                 Err(e) => {
                     warn!("Start failed: {}", e);
-                    failure.store(true, Ordering::SeqCst);
+                    pending.failure.store(true, Ordering::SeqCst);
                 }
             }
 
@@ -446,22 +642,15 @@ impl ScreenCastPortal {
     fn setup_start_handler(
         conn: &SyncConnection,
         path: dbus::Path<'static>,
-        _session_handle: String,
-        fd: Arc<Mutex<Option<OwnedFd>>>,
-        streams: Arc<Mutex<Vec<StreamInfo>>>,
-        failure: Arc<AtomicBool>,
-        restore_token: Arc<Mutex<Option<String>>>,
+        session_handle: String,
+        pending: PendingSession,
     ) -> Result<()> {
-        let mut rule = MatchRule::new();
-        rule.path = Some(path);
-        rule.msg_type = Some(MessageType::Signal);
-        rule.sender = Some("org.freedesktop.portal.Desktop".into());
-        rule.interface = Some("org.freedesktop.portal.Request".into());
-
-        conn.add_match(rule, move |response: PortalResponse, conn, _msg| {
+        conn.add_match(Self::request_rule(path), move |response: PortalResponse, conn, _msg| {
             if response.response != 0 {
                 warn!("Start failed with code: {}", response.response);
-                failure.store(true, Ordering::SeqCst);
+                // 1 is the user cancelling the dialog
+                pending.denied.store(response.response == 1, Ordering::SeqCst);
+                pending.failure.store(true, Ordering::SeqCst);
                 return true;
             }
 
@@ -470,33 +659,34 @@ impl ScreenCastPortal {
             // Extract restore token if present
             if let Some(token) = response.results.get("restore_token") {
                 if let Some(token_str) = token.as_str() {
-                    *restore_token.lock().unwrap() = Some(token_str.to_string());
+                    *pending.restore_token.lock().unwrap() = Some(token_str.to_string());
                     debug!("Got restore token for future use");
                 }
             }
 
+            // Input devices the user allowed (RemoteDesktop sessions only)
+            if let Some(devices) = response.results.get("devices").and_then(|v| v.as_u64()) {
+                pending.devices.store(devices as u32, Ordering::SeqCst);
+            }
+
             // Extract streams
             let extracted_streams = Self::extract_streams_from_response(&response);
             if extracted_streams.is_empty() {
                 warn!("No streams in Start response");
-                failure.store(true, Ordering::SeqCst);
+                pending.failure.store(true, Ordering::SeqCst);
                 return true;
             }
 
-            *streams.lock().unwrap() = extracted_streams;
+            *pending.streams.lock().unwrap() = extracted_streams;
 
             // Get PipeWire file descriptor
-            let portal = conn.with_proxy(
-                "org.freedesktop.portal.Desktop",
-                "/org/freedesktop/portal/desktop",
-                Duration::from_millis(5000),
-            );
+            let portal = Self::get_portal_proxy(conn);
 
             // OpenPipeWireRemote returns a file descriptor
             let pw_fd_result: std::result::Result<(std::os::unix::io::RawFd,), dbus::Error> = portal.method_call(
-                "org.freedesktop.portal.ScreenCast",
+                SCREENCAST_IFACE,
                 "OpenPipeWireRemote",
-                (dbus::Path::from("/org/freedesktop/portal/desktop"), HashMap::<String, Variant<Box<dyn RefArg>>>::new()),
+                (dbus::Path::from(session_handle.clone()), HashMap::<String, Variant<Box<dyn RefArg>>>::new()),
             );
 
             match pw_fd_result {
@@ -504,11 +694,11 @@ impl ScreenCastPortal {
                     debug!("Got PipeWire FD: {}", raw_fd);
                     // Safety: We trust the portal to give us a valid FD
                     let owned_fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
-                    *fd.lock().unwrap() = Some(owned_fd);
+                    *pending.fd.lock().unwrap() = Some(owned_fd);
                 }
                 Err(e) => {
                     warn!("OpenPipeWireRemote failed: {}", e);
-                    failure.store(true, Ordering::SeqCst);
+                    pending.failure.store(true, Ordering::SeqCst);
                 }
             }
 
@@ -0,0 +1,316 @@
+//! Windows screen capture through DXGI desktop duplication
+//!
+//! [`Duplication`] decides when to recreate the duplication and what to
+//! send meanwhile; [`DxgiDuplicator`] makes the calls. The process turns
+//! per-monitor DPI aware before anything is enumerated, so outputs come
+//! back in physical pixels on every monitor.
+
+use async_trait::async_trait;
+use std::sync::Once;
+use std::time::Duration;
+use tracing::{debug, info};
+use windows::core::ComInterface;
+use windows::Win32::Foundation::{E_ACCESSDENIED, HMODULE};
+use windows::Win32::Graphics::Direct3D::D3D_DRIVER_TYPE_UNKNOWN;
+use windows::Win32::Graphics::Direct3D11::{
+    D3D11CreateDevice, ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_CPU_ACCESS_READ,
+    D3D11_CREATE_DEVICE_BGRA_SUPPORT, D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ, D3D11_SDK_VERSION,
+    D3D11_TEXTURE2D_DESC, D3D11_USAGE_STAGING,
+};
+use windows::Win32::Graphics::Dxgi::{
+    CreateDXGIFactory1, IDXGIAdapter1, IDXGIFactory1, IDXGIOutput1, IDXGIOutputDuplication, IDXGIResource,
+    DXGI_ERROR_ACCESS_DENIED, DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_DEVICE_REMOVED, DXGI_ERROR_DEVICE_RESET,
+    DXGI_ERROR_NOT_FOUND, DXGI_ERROR_SESSION_DISCONNECTED, DXGI_ERROR_WAIT_TIMEOUT, DXGI_OUTDUPL_FRAME_INFO,
+};
+use windows::Win32::System::StationsAndDesktops::{
+    CloseDesktop, OpenInputDesktop, SetThreadDesktop, DESKTOP_ACCESS_FLAGS, DESKTOP_CONTROL_FLAGS, HDESK,
+};
+use windows::Win32::UI::HiDpi::{
+    GetDpiForMonitor, SetProcessDpiAwarenessContext, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2, MDT_EFFECTIVE_DPI,
+};
+
+use super::duplication::{Duplication, DuplicationError, Duplicator, OutputGeometry};
+use super::{DisplayInfo, Frame, PixelFormat, ScreenCapturer};
+use crate::error::Result;
+
+/// How long a capture waits for the screen to change before repeating
+/// the last frame
+const ACQUIRE_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// `GENERIC_ALL`, to switch to the input desktop and duplicate it
+const DESKTOP_ALL_ACCESS: DESKTOP_ACCESS_FLAGS = DESKTOP_ACCESS_FLAGS(0x1000_0000);
+
+/// Sort a failed call by what [`Duplication`] does about it
+fn classify(error: windows::core::Error) -> DuplicationError {
+    let code = error.code();
+    if code == DXGI_ERROR_WAIT_TIMEOUT {
+        DuplicationError::Timeout
+    } else if code == DXGI_ERROR_ACCESS_LOST || code == DXGI_ERROR_SESSION_DISCONNECTED {
+        DuplicationError::AccessLost
+    } else if code == DXGI_ERROR_DEVICE_REMOVED || code == DXGI_ERROR_DEVICE_RESET {
+        DuplicationError::DeviceLost
+    } else if code == E_ACCESSDENIED || code == DXGI_ERROR_ACCESS_DENIED {
+        // What the secure desktop answers anyone but SYSTEM
+        DuplicationError::SecureDesktop
+    } else {
+        DuplicationError::Failed(error.to_string())
+    }
+}
+
+/// Make DXGI and GDI report physical pixels for every monitor; fails
+/// harmlessly when the manifest already set an awareness
+fn enable_per_monitor_dpi() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        if let Err(e) = unsafe { SetProcessDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) } {
+            debug!("DPI awareness left as it was: {}", e);
+        }
+    });
+}
+
+/// A duplication and the device it copies frames with
+struct Session {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    duplication: IDXGIOutputDuplication,
+    /// CPU-readable copy of the last frame, kept while the size holds
+    staging: Option<(u32, u32, ID3D11Texture2D)>,
+}
+
+impl Session {
+    fn copy_frame(&mut self, resource: Option<IDXGIResource>) -> std::result::Result<Frame, DuplicationError> {
+        let resource = resource.ok_or_else(|| DuplicationError::Failed("no desktop image".to_string()))?;
+        let texture: ID3D11Texture2D = resource.cast().map_err(classify)?;
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        unsafe { texture.GetDesc(&mut desc) };
+
+        if !matches!(&self.staging, Some((width, height, _)) if (*width, *height) == (desc.Width, desc.Height)) {
+            let staging_desc = D3D11_TEXTURE2D_DESC {
+                Usage: D3D11_USAGE_STAGING,
+                BindFlags: 0,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ.0 as u32,
+                MiscFlags: 0,
+                ..desc
+            };
+            let mut staging = None;
+            unsafe { self.device.CreateTexture2D(&staging_desc, None, Some(&mut staging)) }.map_err(classify)?;
+            self.staging = staging.map(|texture| (desc.Width, desc.Height, texture));
+        }
+        let Some((width, height, staging)) = &self.staging else {
+            return Err(DuplicationError::Failed("no staging texture".to_string()));
+        };
+        let (width, height) = (*width, *height);
+
+        unsafe { self.context.CopyResource(staging, &texture) };
+        let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+        unsafe { self.context.Map(staging, 0, D3D11_MAP_READ, 0, Some(&mut mapped)) }.map_err(classify)?;
+        let row_bytes = width as usize * 4;
+        let mut data = Vec::with_capacity(row_bytes * height as usize);
+        for row in 0..height as usize {
+            // Rows are padded to the driver's pitch
+            let start = unsafe { (mapped.pData as *const u8).add(row * mapped.RowPitch as usize) };
+            data.extend_from_slice(unsafe { std::slice::from_raw_parts(start, row_bytes) });
+        }
+        unsafe { self.context.Unmap(staging, 0) };
+
+        Ok(Frame {
+            data,
+            width,
+            height,
+            pixel_format: PixelFormat::BGRA,
+            stride: width * 4,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+        })
+    }
+}
+
+/// Desktop duplication through DXGI and Direct3D 11
+#[derive(Default)]
+pub struct DxgiDuplicator {
+    /// Outputs of the last enumeration with the adapter each hangs off
+    found: Vec<(String, IDXGIAdapter1, IDXGIOutput1)>,
+    session: Option<Session>,
+    /// The input desktop this thread was moved to, closed on the next switch
+    desktop: Option<HDESK>,
+}
+
+// Direct3D 11 devices and DXGI objects are free-threaded, and the
+// capturer is only ever used behind the capture lock
+unsafe impl Send for DxgiDuplicator {}
+unsafe impl Sync for DxgiDuplicator {}
+
+impl DxgiDuplicator {
+    /// Move the calling thread to the desktop that has input, which the
+    /// duplication is created on. The secure desktop refuses anyone but
+    /// SYSTEM.
+    fn attach_input_desktop(&mut self) -> std::result::Result<(), DuplicationError> {
+        let desktop = unsafe { OpenInputDesktop(DESKTOP_CONTROL_FLAGS(0), false, DESKTOP_ALL_ACCESS) }.map_err(classify)?;
+        if let Err(e) = unsafe { SetThreadDesktop(desktop) } {
+            let _ = unsafe { CloseDesktop(desktop) };
+            return Err(classify(e));
+        }
+        if let Some(previous) = self.desktop.replace(desktop) {
+            let _ = unsafe { CloseDesktop(previous) };
+        }
+        Ok(())
+    }
+}
+
+impl Duplicator for DxgiDuplicator {
+    fn outputs(&mut self) -> std::result::Result<Vec<OutputGeometry>, DuplicationError> {
+        self.found.clear();
+        let factory: IDXGIFactory1 = unsafe { CreateDXGIFactory1() }.map_err(classify)?;
+        let mut outputs = Vec::new();
+        for adapter_index in 0u32.. {
+            let adapter = match unsafe { factory.EnumAdapters1(adapter_index) } {
+                Ok(adapter) => adapter,
+                Err(e) if e.code() == DXGI_ERROR_NOT_FOUND => break,
+                Err(e) => return Err(classify(e)),
+            };
+            for output_index in 0u32.. {
+                let output = match unsafe { adapter.EnumOutputs(output_index) } {
+                    Ok(output) => output,
+                    Err(e) if e.code() == DXGI_ERROR_NOT_FOUND => break,
+                    Err(e) => return Err(classify(e)),
+                };
+                let desc = unsafe { output.GetDesc() }.map_err(classify)?;
+                if !desc.AttachedToDesktop.as_bool() {
+                    continue;
+                }
+
+                let (mut dpi_x, mut dpi_y) = (96, 96);
+                if unsafe { GetDpiForMonitor(desc.Monitor, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y) }.is_err() {
+                    dpi_x = 96;
+                }
+                let name = String::from_utf16_lossy(&desc.DeviceName).trim_end_matches('\0').to_string();
+                let rect = desc.DesktopCoordinates;
+                outputs.push(OutputGeometry {
+                    name: name.clone(),
+                    left: rect.left,
+                    top: rect.top,
+                    width: (rect.right - rect.left) as u32,
+                    height: (rect.bottom - rect.top) as u32,
+                    dpi: dpi_x,
+                });
+                self.found.push((name, adapter.clone(), output.cast().map_err(classify)?));
+            }
+        }
+        Ok(outputs)
+    }
+
+    fn open(&mut self, output: &OutputGeometry, new_device: bool) -> std::result::Result<(), DuplicationError> {
+        self.close();
+        self.attach_input_desktop()?;
+        let Some((_, adapter, dxgi_output)) = self.found.iter().find(|(name, ..)| *name == output.name) else {
+            return Err(DuplicationError::Failed(format!("{} is no longer attached", output.name)));
+        };
+
+        // Adapters come fresh from each enumeration, so every duplication
+        // gets its own device; after a loss that is the point
+        if new_device {
+            info!("Creating a new Direct3D device for {}", output.name);
+        }
+        let (mut device, mut context) = (None, None);
+        unsafe {
+            D3D11CreateDevice(
+                adapter,
+                D3D_DRIVER_TYPE_UNKNOWN,
+                HMODULE::default(),
+                D3D11_CREATE_DEVICE_BGRA_SUPPORT,
+                None,
+                D3D11_SDK_VERSION,
+                Some(&mut device),
+                None,
+                Some(&mut context),
+            )
+        }
+        .map_err(classify)?;
+        let (Some(device), Some(context)) = (device, context) else {
+            return Err(DuplicationError::Failed("Direct3D returned no device".to_string()));
+        };
+
+        let duplication = unsafe { dxgi_output.DuplicateOutput(&device) }.map_err(classify)?;
+        debug!("Duplicating {} at {}x{}+{}+{}", output.name, output.width, output.height, output.left, output.top);
+        self.session = Some(Session { device, context, duplication, staging: None });
+        Ok(())
+    }
+
+    fn acquire(&mut self, timeout: Duration) -> std::result::Result<Frame, DuplicationError> {
+        let session = self.session.as_mut().ok_or_else(|| DuplicationError::Failed("not duplicating".to_string()))?;
+        let mut info = DXGI_OUTDUPL_FRAME_INFO::default();
+        let mut resource: Option<IDXGIResource> = None;
+        unsafe { session.duplication.AcquireNextFrame(timeout.as_millis() as u32, &mut info, &mut resource) }
+            .map_err(classify)?;
+        let frame = session.copy_frame(resource);
+        // Released whether or not the copy worked, or the next acquire fails
+        let _ = unsafe { session.duplication.ReleaseFrame() };
+        frame
+    }
+
+    fn close(&mut self) {
+        self.session = None;
+    }
+}
+
+/// Screen capture of one monitor through DXGI desktop duplication
+pub struct DxgiCapturer {
+    duplication: Duplication<DxgiDuplicator>,
+}
+
+impl DxgiCapturer {
+    pub async fn new() -> Result<Self> {
+        enable_per_monitor_dpi();
+        Ok(Self { duplication: Duplication::new(DxgiDuplicator::default()) })
+    }
+}
+
+#[async_trait]
+impl ScreenCapturer for DxgiCapturer {
+    async fn initialize(&mut self) -> Result<()> {
+        self.duplication.open()?;
+        if let Some(output) = self.duplication.output() {
+            info!(
+                "Capturing {} ({}x{} at {}%)",
+                output.name, output.width, output.height, output.scale_percent()
+            );
+        }
+        Ok(())
+    }
+
+    async fn capture_frame(&mut self) -> Result<Frame> {
+        self.duplication.capture(ACQUIRE_TIMEOUT)
+    }
+
+    fn get_display_info(&self) -> Vec<DisplayInfo> {
+        self.duplication
+            .outputs()
+            .iter()
+            .enumerate()
+            .map(|(id, output)| output.display_info(id as u32))
+            .collect()
+    }
+
+    fn select_display(&mut self, display_id: u32) -> Result<()> {
+        self.duplication.select(display_id as usize)
+    }
+
+    fn set_capture_region(&mut self, x: i32, y: i32, width: u32, height: u32) -> Result<()> {
+        self.duplication.set_region(x, y, width, height)
+    }
+
+    fn get_resolution(&self) -> (u32, u32) {
+        self.duplication.resolution()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.duplication.is_healthy()
+    }
+
+    async fn cleanup(&mut self) -> Result<()> {
+        self.duplication.close();
+        Ok(())
+    }
+}
@@ -5,18 +5,25 @@ use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 use x11rb::connection::Connection;
 use x11rb::protocol::damage::{self, ConnectionExt as DamageExt};
-use x11rb::protocol::shm::ConnectionExt as ShmExt;
+use x11rb::protocol::randr::{self, ConnectionExt as RandrExt};
+use x11rb::protocol::shm::{self, ConnectionExt as ShmExt};
 use x11rb::protocol::xfixes::ConnectionExt as XfixesExt;
 use x11rb::protocol::xproto::{self, ConnectionExt as _};
 use x11rb::protocol::Event;
 use x11rb::rust_connection::RustConnection;
 
-use crate::capture::{DisplayInfo, Frame, PixelFormat, ScreenCapturer};
+use crate::capture::cursor::{CursorImage, CursorSample};
+use crate::capture::damage::DamageAccumulator;
+use crate::capture::frame_diff::DirtyRect;
+use crate::capture::{BufferPath, DisplayInfo, Frame, PixelFormat, ScreenCapturer};
 use crate::error::{CaptureError, GhostLinkError, Result};
 
 const TARGET_FPS: u32 = 60;
 const FRAME_TIME_NS: u64 = 1_000_000_000 / TARGET_FPS as u64;
 
+/// Full grab now and then, in case a client drew without reporting damage
+const FULL_GRAB_INTERVAL: Duration = Duration::from_secs(5);
+
 /// High-performance X11 screen capturer with XDamage for 60fps
 pub struct X11FastCapturer {
     connection: Arc<RustConnection>,
@@ -26,18 +33,79 @@ pub struct X11FastCapturer {
     height: u32,
     damage: Option<damage::Damage>,
     shm_available: bool,
+    /// Segment the X server writes grabs into; `None` falls back to GetImage
+    shm_segment: Option<ShmSegment>,
+    /// XFixes answered, so the cursor can be read for streaming
+    cursor_available: bool,
     last_frame_time: Instant,
+    last_full_grab: Instant,
     frame_buffer: Arc<Mutex<Vec<u8>>>,
-    damage_regions: Arc<Mutex<Vec<DamageRegion>>>,
+    /// Damage reported since the last grab
+    pending_damage: DamageAccumulator,
+    /// Regions the last frame updated; `None` without XDamage
+    frame_damage: Option<Vec<DirtyRect>>,
+    /// RandR reported a change the capture loop hasn't asked about yet
+    display_changed: bool,
     is_initialized: bool,
 }
 
-#[derive(Debug, Clone)]
-struct DamageRegion {
-    x: i16,
-    y: i16,
-    width: u16,
-    height: u16,
+/// System V shared memory the X server writes grabs into
+struct ShmSegment {
+    connection: Arc<RustConnection>,
+    seg: shm::Seg,
+    addr: *mut libc::c_void,
+    size: usize,
+}
+
+// The mapping is only read after the X server answered the grab that
+// filled it, and only through the capturer that owns it
+unsafe impl Send for ShmSegment {}
+unsafe impl Sync for ShmSegment {}
+
+impl ShmSegment {
+    /// Create a segment of `size` bytes and attach it to the X server; fails
+    /// for remote displays, which cannot see our memory
+    fn attach(connection: &Arc<RustConnection>, size: usize) -> Result<Self> {
+        let failed = |reason: String| GhostLinkError::Capture(CaptureError::InitializationFailed { reason });
+        
+        let shmid = unsafe { libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600) };
+        if shmid < 0 {
+            return Err(failed(format!("shmget failed: {}", std::io::Error::last_os_error())));
+        }
+        let addr = unsafe { libc::shmat(shmid, std::ptr::null(), 0) };
+        if addr as isize == -1 {
+            let error = std::io::Error::last_os_error();
+            unsafe { libc::shmctl(shmid, libc::IPC_RMID, std::ptr::null_mut()) };
+            return Err(failed(format!("shmat failed: {}", error)));
+        }
+        
+        let attached = connection.generate_id().map_err(GhostLinkError::from).and_then(|seg| {
+            connection.shm_attach(seg, shmid as u32, false)?.check()?;
+            Ok(seg)
+        });
+        // Marked for removal now, the segment goes away once both sides detach
+        unsafe { libc::shmctl(shmid, libc::IPC_RMID, std::ptr::null_mut()) };
+        
+        match attached {
+            Ok(seg) => Ok(Self { connection: Arc::clone(connection), seg, addr, size }),
+            Err(e) => {
+                unsafe { libc::shmdt(addr) };
+                Err(failed(format!("X server cannot attach shared memory: {}", e)))
+            }
+        }
+    }
+    
+    fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.size) }
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        let _ = self.connection.shm_detach(self.seg);
+        let _ = self.connection.flush();
+        unsafe { libc::shmdt(self.addr) };
+    }
 }
 
 impl X11FastCapturer {
@@ -74,9 +142,14 @@ impl X11FastCapturer {
             height,
             damage: None,
             shm_available,
+            shm_segment: None,
+            cursor_available: false,
             last_frame_time: Instant::now(),
+            last_full_grab: Instant::now(),
             frame_buffer: Arc::new(Mutex::new(vec![0u8; (width * height * 4) as usize])),
-            damage_regions: Arc::new(Mutex::new(Vec::new())),
+            pending_damage: DamageAccumulator::new(width, height),
+            frame_damage: None,
+            display_changed: false,
             is_initialized: false,
         })
     }
@@ -131,162 +204,177 @@ impl X11FastCapturer {
         }
     }
     
-    /// Capture using MIT-SHM for maximum performance
-    async fn capture_shm(&self) -> Result<Vec<u8>> {
-        // For now, fallback to regular capture
-        // TODO: Implement shared memory capture
-        self.capture_regular().await
+    /// Ask RandR to tell us about resolution changes
+    fn init_randr(&self) -> Result<()> {
+        let reply = self.connection.randr_query_version(1, 2)?.reply()?;
+        debug!("RandR version: {}.{}", reply.major_version, reply.minor_version);
+        // Outputs and CRTCs change on hot-plug even when the screen keeps its size
+        let mask = randr::NotifyMask::SCREEN_CHANGE | randr::NotifyMask::CRTC_CHANGE | randr::NotifyMask::OUTPUT_CHANGE;
+        self.connection.randr_select_input(self.root, mask)?.check()?;
+        Ok(())
     }
     
-    /// Regular X11 capture without SHM
-    async fn capture_regular(&self) -> Result<Vec<u8>> {
-        let image = self.connection.get_image(
-            xproto::ImageFormat::Z_PIXMAP,
-            self.root,
-            0,
-            0,
-            self.width as u16,
-            self.height as u16,
-            !0,
-        )?.reply()
-        .map_err(|e| GhostLinkError::Capture(CaptureError::CaptureFailed {
-            reason: format!("Failed to get X11 image: {}", e),
-        }))?;
-        
-        // Convert BGRA to RGBA
-        let mut rgba_data = vec![0u8; (self.width * self.height * 4) as usize];
-        for i in (0..image.data.len()).step_by(4) {
-            rgba_data[i] = image.data[i + 2];     // R
-            rgba_data[i + 1] = image.data[i + 1]; // G
-            rgba_data[i + 2] = image.data[i];     // B
-            rgba_data[i + 3] = image.data[i + 3]; // A
+    /// Attach a shared memory segment the size of the screen, if the
+    /// server can share memory with us
+    fn attach_shm(&mut self) {
+        self.shm_segment = None;
+        if !self.shm_available {
+            return;
+        }
+        match ShmSegment::attach(&self.connection, (self.width * self.height * 4) as usize) {
+            Ok(segment) => {
+                info!("MIT-SHM segment attached for {}x{}", self.width, self.height);
+                self.shm_segment = Some(segment);
+            }
+            Err(e) => warn!("MIT-SHM unusable, falling back to GetImage: {}", e),
         }
-        
-        Ok(rgba_data)
     }
     
-    /// Process damage events for incremental updates
-    async fn process_damage_events(&self) -> Vec<DamageRegion> {
-        let mut regions = Vec::new();
-        
-        // Poll for damage events without blocking
+    /// Gather damage and resolution changes reported since the last frame
+    fn process_events(&mut self) {
+        let mut resized = false;
         while let Ok(Some(event)) = self.connection.poll_for_event() {
             match event {
                 Event::DamageNotify(notify) => {
-                    regions.push(DamageRegion {
-                        x: notify.area.x,
-                        y: notify.area.y,
-                        width: notify.area.width,
-                        height: notify.area.height,
-                    });
+                    let area = notify.area;
+                    self.pending_damage.add(area.x as i32, area.y as i32, area.width as u32, area.height as u32);
                 }
+                Event::RandrScreenChangeNotify(_) => {
+                    resized = true;
+                    self.display_changed = true;
+                }
+                Event::RandrNotify(_) => self.display_changed = true,
                 _ => {}
             }
         }
         
-        // Merge overlapping regions for efficiency
-        Self::merge_damage_regions(&mut regions);
-        
-        regions
+        if resized {
+            if let Err(e) = self.handle_resize() {
+                warn!("Failed to follow X11 screen resize: {}", e);
+            }
+        }
     }
     
-    /// Merge overlapping damage regions
-    fn merge_damage_regions(regions: &mut Vec<DamageRegion>) {
-        if regions.len() <= 1 {
-            return;
+    /// Follow a RandR resolution change with a frame buffer and shared
+    /// memory segment of the new size
+    fn handle_resize(&mut self) -> Result<()> {
+        let geometry = self.connection.get_geometry(self.root)?.reply()?;
+        let (width, height) = (geometry.width as u32, geometry.height as u32);
+        if (width, height) == (self.width, self.height) {
+            return Ok(());
         }
         
-        // Simple merge algorithm - can be optimized
-        let mut merged = Vec::new();
-        for region in regions.drain(..) {
-            let mut was_merged = false;
-            for existing in &mut merged {
-                if Self::regions_overlap(&region, existing) {
-                    Self::expand_region(existing, &region);
-                    was_merged = true;
-                    break;
-                }
-            }
-            if !was_merged {
-                merged.push(region);
-            }
+        info!("X11 screen resized from {}x{} to {}x{}", self.width, self.height, width, height);
+        self.width = width;
+        self.height = height;
+        *self.frame_buffer.lock() = vec![0u8; (width * height * 4) as usize];
+        self.pending_damage.resize(width, height);
+        if self.shm_segment.is_some() {
+            self.attach_shm();
         }
-        *regions = merged;
-    }
-    
-    fn regions_overlap(a: &DamageRegion, b: &DamageRegion) -> bool {
-        !(a.x + a.width as i16 <= b.x || 
-          b.x + b.width as i16 <= a.x ||
-          a.y + a.height as i16 <= b.y ||
-          b.y + b.height as i16 <= a.y)
+        Ok(())
     }
     
-    fn expand_region(target: &mut DamageRegion, source: &DamageRegion) {
-        let min_x = target.x.min(source.x);
-        let min_y = target.y.min(source.y);
-        let max_x = (target.x + target.width as i16).max(source.x + source.width as i16);
-        let max_y = (target.y + target.height as i16).max(source.y + source.height as i16);
-        
-        target.x = min_x;
-        target.y = min_y;
-        target.width = (max_x - min_x) as u16;
-        target.height = (max_y - min_y) as u16;
+    /// Bring the frame buffer up to date with the screen, grabbing only
+    /// what XDamage reported as changed
+    fn grab_pending(&mut self) -> Result<()> {
+        self.process_events();
+        if self.damage.is_none() || self.last_full_grab.elapsed() >= FULL_GRAB_INTERVAL {
+            self.pending_damage.mark_full();
+        }
+        let rects = self.pending_damage.take();
+        
+        // Drawing during the grab is reported again for the next frame
+        if let Some(damage_id) = self.damage {
+            let _ = self.connection.damage_subtract(damage_id, 0u32, 0u32);
+        }
+        self.grab(&rects)?;
+        
+        if rects.first().is_some_and(|rect| rect.width == self.width && rect.height == self.height) {
+            self.last_full_grab = Instant::now();
+        }
+        self.frame_damage = self.damage.map(|_| rects);
+        Ok(())
     }
     
-    /// Capture only damaged regions for incremental updates
-    async fn capture_damage_regions(&self, regions: &[DamageRegion]) -> Result<()> {
-        let mut buffer = self.frame_buffer.lock();
-        
-        for region in regions {
-            // Clamp region to screen bounds
-            let x = region.x.max(0) as u16;
-            let y = region.y.max(0) as u16;
-            let width = region.width.min(self.width as u16 - x);
-            let height = region.height.min(self.height as u16 - y);
-            
-            if width == 0 || height == 0 {
-                continue;
-            }
-            
-            // Capture the damaged region
-            let image = self.connection.get_image(
-                xproto::ImageFormat::Z_PIXMAP,
-                self.root,
-                x as i16,
-                y as i16,
-                width,
-                height,
-                !0,
-            )?.reply()
-            .map_err(|e| GhostLinkError::Capture(CaptureError::CaptureFailed {
-                reason: format!("Failed to capture damage region: {}", e),
-            }))?;
-            
-            // Update the frame buffer with the damaged region
-            let bytes_per_pixel = 4;
-            let _stride = self.width * bytes_per_pixel;
-            
-            for row in 0..height as usize {
-                let src_offset = row * (width as usize) * bytes_per_pixel as usize;
-                let dst_offset = ((y as usize + row) * self.width as usize + x as usize) * bytes_per_pixel as usize;
-                
-                // Convert BGRA to RGBA while copying
-                for col in 0..width as usize {
-                    let src_idx = src_offset + col * 4;
-                    let dst_idx = dst_offset + col * 4;
-                    
-                    buffer[dst_idx] = image.data[src_idx + 2];     // R
-                    buffer[dst_idx + 1] = image.data[src_idx + 1]; // G
-                    buffer[dst_idx + 2] = image.data[src_idx];     // B
-                    buffer[dst_idx + 3] = image.data[src_idx + 3]; // A
+    /// Copy `rects` of the screen into the frame buffer
+    fn grab(&mut self, rects: &[DirtyRect]) -> Result<()> {
+        for rect in rects {
+            if let Some(segment) = &self.shm_segment {
+                match self.grab_shm(segment, rect) {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        warn!("MIT-SHM grab failed, falling back to GetImage: {}", e);
+                        self.shm_segment = None;
+                    }
                 }
             }
+            self.grab_regular(rect)?;
         }
+        if !rects.is_empty() {
+            debug!("Grabbed {} damaged regions", rects.len());
+        }
+        Ok(())
+    }
+    
+    /// Grab through the shared memory segment, without copying the pixels
+    /// through the socket
+    fn grab_shm(&self, segment: &ShmSegment, rect: &DirtyRect) -> Result<()> {
+        self.connection.shm_get_image(
+            self.root,
+            rect.x as i16,
+            rect.y as i16,
+            rect.width as u16,
+            rect.height as u16,
+            !0,
+            xproto::ImageFormat::Z_PIXMAP.into(),
+            segment.seg,
+            0,
+        )?.reply()
+        .map_err(|e| GhostLinkError::Capture(CaptureError::CaptureFailed {
+            reason: format!("Failed to get X11 image through MIT-SHM: {}", e),
+        }))?;
+        
+        let len = (rect.width * rect.height * 4) as usize;
+        copy_bgra_rect(&segment.data()[..len], &mut self.frame_buffer.lock(), self.width, rect);
+        Ok(())
+    }
+    
+    /// Regular X11 capture without SHM
+    fn grab_regular(&self, rect: &DirtyRect) -> Result<()> {
+        let image = self.connection.get_image(
+            xproto::ImageFormat::Z_PIXMAP,
+            self.root,
+            rect.x as i16,
+            rect.y as i16,
+            rect.width as u16,
+            rect.height as u16,
+            !0,
+        )?.reply()
+        .map_err(|e| GhostLinkError::Capture(CaptureError::CaptureFailed {
+            reason: format!("Failed to get X11 image: {}", e),
+        }))?;
         
+        copy_bgra_rect(&image.data, &mut self.frame_buffer.lock(), self.width, rect);
         Ok(())
     }
 }
 
+/// Copy a packed BGRA grab of `rect` into the RGBA frame buffer
+fn copy_bgra_rect(src: &[u8], dst: &mut [u8], dst_width: u32, rect: &DirtyRect) {
+    let row_len = rect.width as usize * 4;
+    for (row, src_row) in src.chunks_exact(row_len).take(rect.height as usize).enumerate() {
+        let start = ((rect.y as usize + row) * dst_width as usize + rect.x as usize) * 4;
+        let Some(dst_row) = dst.get_mut(start..start + row_len) else {
+            break;
+        };
+        for (dst_pixel, src_pixel) in dst_row.chunks_exact_mut(4).zip(src_row.chunks_exact(4)) {
+            // Depth-24 visuals leave the fourth byte undefined
+            dst_pixel.copy_from_slice(&[src_pixel[2], src_pixel[1], src_pixel[0], 255]);
+        }
+    }
+}
+
 #[async_trait]
 impl ScreenCapturer for X11FastCapturer {
     async fn initialize(&mut self) -> Result<()> {
@@ -300,6 +388,7 @@ impl ScreenCapturer for X11FastCapturer {
             Ok(cookie) => {
                 if let Ok(reply) = cookie.reply() {
                     info!("XFixes version: {}.{}", reply.major_version, reply.minor_version);
+                    self.cursor_available = true;
                 }
             }
             Err(e) => {
@@ -307,14 +396,16 @@ impl ScreenCapturer for X11FastCapturer {
             }
         }
         
-        // Capture initial frame
-        let initial_frame = if self.shm_available {
-            self.capture_shm().await?
-        } else {
-            self.capture_regular().await?
-        };
+        if let Err(e) = self.init_randr() {
+            warn!("RandR not available, resolution changes will not be followed: {}", e);
+        }
+        
+        self.attach_shm();
         
-        *self.frame_buffer.lock() = initial_frame;
+        // Capture initial frame
+        let rects = self.pending_damage.take();
+        self.grab(&rects)?;
+        self.last_full_grab = Instant::now();
         
         self.is_initialized = true;
         info!("X11 fast capturer initialized successfully");
@@ -335,39 +426,7 @@ impl ScreenCapturer for X11FastCapturer {
             tokio::time::sleep(Duration::from_nanos(FRAME_TIME_NS - elapsed.as_nanos() as u64)).await;
         }
         
-        // Process damage events if available
-        if self.damage.is_some() {
-            let damage_regions = self.process_damage_events().await;
-            
-            if !damage_regions.is_empty() {
-                debug!("Processing {} damage regions", damage_regions.len());
-                self.capture_damage_regions(&damage_regions).await?;
-            } else {
-                // No damage, capture full frame every second as fallback
-                if elapsed.as_secs() >= 1 {
-                    let full_frame = if self.shm_available {
-                        self.capture_shm().await?
-                    } else {
-                        self.capture_regular().await?
-                    };
-                    *self.frame_buffer.lock() = full_frame;
-                }
-            }
-            
-            // Clear damage
-            if let Some(damage_id) = self.damage {
-                let _ = self.connection.damage_subtract(damage_id, 0u32, 0u32);
-            }
-        } else {
-            // No damage tracking, capture full frame
-            let full_frame = if self.shm_available {
-                self.capture_shm().await?
-            } else {
-                self.capture_regular().await?
-            };
-            *self.frame_buffer.lock() = full_frame;
-        }
-        
+        self.grab_pending()?;
         self.last_frame_time = now;
         
         // Return the current frame buffer
@@ -416,6 +475,46 @@ impl ScreenCapturer for X11FastCapturer {
         self.is_initialized && self.connection.setup().roots.len() > 0
     }
     
+    fn take_damage(&mut self) -> Option<Vec<DirtyRect>> {
+        self.frame_damage.take()
+    }
+    
+    fn take_display_change(&mut self) -> bool {
+        // Frames may be held back while a change settles, so events are
+        // read here too
+        self.process_events();
+        std::mem::take(&mut self.display_changed)
+    }
+    
+    async fn reconfigure(&mut self) -> Result<()> {
+        self.handle_resize()
+    }
+    
+    fn buffer_path(&self) -> BufferPath {
+        if self.shm_segment.is_some() {
+            BufferPath::Shm
+        } else {
+            BufferPath::Copy
+        }
+    }
+    
+    fn sample_cursor(&mut self) -> Option<CursorSample> {
+        // XGetImage leaves the cursor out of the frames, so it is read separately
+        if !self.cursor_available {
+            return None;
+        }
+        let reply = self.connection.xfixes_get_cursor_image().ok()?.reply().ok()?;
+        let image = CursorImage::from_argb(
+            reply.cursor_serial as u64,
+            reply.width as u32,
+            reply.height as u32,
+            reply.xhot as u32,
+            reply.yhot as u32,
+            &reply.cursor_image,
+        );
+        Some(CursorSample { x: reply.x as i32, y: reply.y as i32, visible: true, image: Some(image) })
+    }
+    
     async fn cleanup(&mut self) -> Result<()> {
         info!("Cleaning up X11 fast capturer");
         
@@ -423,9 +522,88 @@ impl ScreenCapturer for X11FastCapturer {
         if let Some(damage_id) = self.damage {
             let _ = self.connection.damage_destroy(damage_id);
         }
+        self.shm_segment = None;
         
         self.is_initialized = false;
         Ok(())
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11rb::wrapper::ConnectionExt as _;
+    
+    #[test]
+    fn test_bgra_grab_lands_in_place() {
+        // 2x2 region at (1, 1) of a 4x3 frame
+        let src = [
+            1, 2, 3, 0, 4, 5, 6, 0,
+            7, 8, 9, 0, 10, 11, 12, 0,
+        ];
+        let mut dst = vec![0u8; 4 * 3 * 4];
+        copy_bgra_rect(&src, &mut dst, 4, &DirtyRect { x: 1, y: 1, width: 2, height: 2 });
+        
+        let pixel = |x: usize, y: usize| &dst[(y * 4 + x) * 4..(y * 4 + x) * 4 + 4];
+        assert_eq!(pixel(1, 1), [3, 2, 1, 255]);
+        assert_eq!(pixel(2, 1), [6, 5, 4, 255]);
+        assert_eq!(pixel(1, 2), [9, 8, 7, 255]);
+        assert_eq!(pixel(2, 2), [12, 11, 10, 255]);
+        assert_eq!(pixel(0, 1), [0, 0, 0, 0]);
+        assert_eq!(pixel(3, 2), [0, 0, 0, 0]);
+    }
+    
+    /// Grab cost of the old full-frame GetImage path against MIT-SHM with
+    /// XDamage, on a live display:
+    ///
+    /// ```text
+    /// Xvfb :99 -screen 0 3840x2160x24 &
+    /// DISPLAY=:99 cargo test --release -p ghostlink-client x11_capture_benchmark -- --ignored --nocapture
+    /// ```
+    #[tokio::test]
+    #[ignore = "needs an X display, e.g. Xvfb"]
+    async fn x11_capture_benchmark() {
+        const FRAMES: u32 = 60;
+        
+        let mut capturer = X11FastCapturer::new().await.expect("no X display");
+        capturer.initialize().await.unwrap();
+        let full = DirtyRect { x: 0, y: 0, width: capturer.width, height: capturer.height };
+        println!("{}x{}, MIT-SHM {}", full.width, full.height, if capturer.shm_segment.is_some() { "attached" } else { "unavailable" });
+        
+        let started = Instant::now();
+        for _ in 0..FRAMES {
+            capturer.grab_regular(&full).unwrap();
+        }
+        println!("full GetImage:         {:?}/frame", started.elapsed() / FRAMES);
+        
+        if let Some(segment) = capturer.shm_segment.take() {
+            let started = Instant::now();
+            for _ in 0..FRAMES {
+                capturer.grab_shm(&segment, &full).unwrap();
+            }
+            println!("full MIT-SHM:          {:?}/frame", started.elapsed() / FRAMES);
+            capturer.shm_segment = Some(segment);
+        }
+        
+        // Nothing draws, so XDamage reports nothing to grab
+        capturer.grab_pending().unwrap();
+        let started = Instant::now();
+        for _ in 0..FRAMES {
+            capturer.grab_pending().unwrap();
+        }
+        println!("static screen, damage: {:?}/frame", started.elapsed() / FRAMES);
+        
+        // A 256x256 square redrawn every frame
+        let gc = capturer.connection.generate_id().unwrap();
+        capturer.connection.create_gc(gc, capturer.root, &xproto::CreateGCAux::new().foreground(0xff0000)).unwrap();
+        let started = Instant::now();
+        for _ in 0..FRAMES {
+            capturer.connection.poly_fill_rectangle(capturer.root, gc, &[xproto::Rectangle { x: 64, y: 64, width: 256, height: 256 }]).unwrap();
+            capturer.connection.sync().unwrap();
+            capturer.grab_pending().unwrap();
+        }
+        println!("small update, damage:  {:?}/frame", started.elapsed() / FRAMES);
+        capturer.cleanup().await.unwrap();
+    }
+}
@@ -0,0 +1,461 @@
+//! In-session chat with the technician
+//!
+//! The technician's messages arrive as `ChatMessage`. They are shown in the
+//! end user's chat window if one is open and answered with a `read`
+//! receipt; otherwise they wait for the window, with a `delivered` receipt
+//! so the technician knows the machine has them, and are shown and marked
+//! read when it opens. Receipts for the end user's own messages come back
+//! from the relay and only ever move a message forward.
+//!
+//! Without a GUI the console the agent runs in is the chat window, and
+//! replies are written with `ghostlink-client chat send`, which leaves them
+//! in a spool directory the running agent picks up.
+
+#![allow(dead_code)]
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::connection::RelayMessage;
+
+/// Longest message body, in characters; the relay enforces the same
+pub const MAX_CHAT_BODY: usize = 4000;
+
+/// Messages kept while no chat window is open; the oldest are dropped beyond this
+const MAX_PENDING: usize = 200;
+
+/// Own messages whose receipts are tracked
+const TRACKED_MESSAGES: usize = 500;
+
+/// Who wrote a chat message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatParty {
+    Technician,
+    EndUser,
+}
+
+/// How far a message got; only ever moves forward
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    /// Accepted by the relay
+    Sent,
+    /// Shown, or queued until the recipient's chat window opens
+    Delivered,
+    Read,
+}
+
+impl ReceiptStatus {
+    /// Move to `next` if that is further along; returns whether it moved
+    pub fn advance(&mut self, next: ReceiptStatus) -> bool {
+        if next > *self {
+            *self = next;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Trim `body` and drop control characters other than newlines and tabs.
+///
+/// Fails on bodies that are empty afterwards or longer than [`MAX_CHAT_BODY`].
+pub fn sanitize_body(body: &str) -> Result<String> {
+    let body: String = body.chars().filter(|c| !c.is_control() || matches!(c, '\n' | '\t')).collect();
+    let body = body.trim().to_string();
+    if body.is_empty() {
+        bail!("Message is empty");
+    }
+    if body.chars().count() > MAX_CHAT_BODY {
+        bail!("Message is longer than {} characters", MAX_CHAT_BODY);
+    }
+    Ok(body)
+}
+
+/// A message from the technician
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub session_id: String,
+    pub message_id: String,
+    pub sender: String,
+    pub body: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Where the end user reads chat
+pub trait ChatWindow: Send + Sync {
+    fn show(&self, message: &ChatMessage);
+
+    /// A message of the end user's moved forward
+    fn receipt(&self, _message_id: &str, _status: ReceiptStatus) {}
+
+    /// A message of the end user's was refused by the relay
+    fn error(&self, _message_id: &str, _error: &str) {}
+}
+
+/// The console the agent was started from
+pub struct ConsoleChat;
+
+impl ChatWindow for ConsoleChat {
+    fn show(&self, message: &ChatMessage) {
+        println!("💬 [{}] {}: {}", message.sent_at.format("%H:%M"), message.sender, message.body);
+    }
+
+    fn error(&self, message_id: &str, error: &str) {
+        println!("💬 Message {} was not sent: {}", message_id, error);
+    }
+}
+
+struct TrackedMessage {
+    session_id: String,
+    message_id: String,
+    status: ReceiptStatus,
+}
+
+/// Chat of every session on this agent
+pub struct ChatManager {
+    /// Name the end user's messages are signed with
+    sender: String,
+    outbox: mpsc::Sender<RelayMessage>,
+    window: Mutex<Option<Arc<dyn ChatWindow>>>,
+    /// Technician messages waiting for a window, oldest first
+    pending: Mutex<VecDeque<ChatMessage>>,
+    /// The end user's recent messages, oldest first
+    sent: Mutex<VecDeque<TrackedMessage>>,
+}
+
+impl ChatManager {
+    pub fn new(sender: String, outbox: mpsc::Sender<RelayMessage>) -> Self {
+        Self {
+            sender,
+            outbox,
+            window: Mutex::new(None),
+            pending: Mutex::new(VecDeque::new()),
+            sent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Handle a chat message from the relay
+    pub async fn handle_message(&self, message: RelayMessage) -> Result<()> {
+        match message {
+            RelayMessage::ChatMessage { session_id, message_id, sender, body, sent_at, .. } => {
+                let message = ChatMessage {
+                    session_id,
+                    message_id,
+                    sender: sender.unwrap_or_else(|| "Technician".to_string()),
+                    body,
+                    sent_at: sent_at.unwrap_or_else(Utc::now),
+                };
+                let window = self.window.lock().clone();
+                let status = match window {
+                    Some(window) => {
+                        window.show(&message);
+                        ReceiptStatus::Read
+                    }
+                    None => {
+                        let mut pending = self.pending.lock();
+                        if pending.len() >= MAX_PENDING {
+                            pending.pop_front();
+                            warn!("No chat window is open; dropped the oldest unread message");
+                        }
+                        pending.push_back(message.clone());
+                        ReceiptStatus::Delivered
+                    }
+                };
+                self.acknowledge(&message, status).await;
+            }
+            RelayMessage::ChatReceipt { message_id, status, .. } => {
+                let moved = {
+                    let mut sent = self.sent.lock();
+                    sent.iter_mut()
+                        .find(|tracked| tracked.message_id == message_id)
+                        .is_some_and(|tracked| tracked.status.advance(status))
+                };
+                if moved {
+                    if let Some(window) = self.window.lock().clone() {
+                        window.receipt(&message_id, status);
+                    }
+                }
+            }
+            RelayMessage::ChatError { session_id, message_id, error } => {
+                warn!("Chat message {} of session {} was refused: {}", message_id, session_id, error);
+                self.sent.lock().retain(|tracked| tracked.message_id != message_id);
+                if let Some(window) = self.window.lock().clone() {
+                    window.error(&message_id, &error);
+                }
+            }
+            other => bail!("Not a chat message: {:?}", other),
+        }
+        Ok(())
+    }
+
+    /// Show chat in `window` from now on, starting with the messages that
+    /// waited for it
+    pub async fn open_window(&self, window: Arc<dyn ChatWindow>) {
+        *self.window.lock() = Some(Arc::clone(&window));
+        let waiting: Vec<ChatMessage> = self.pending.lock().drain(..).collect();
+        for message in waiting {
+            window.show(&message);
+            self.acknowledge(&message, ReceiptStatus::Read).await;
+        }
+    }
+
+    /// Keep messages for the next window instead
+    pub fn close_window(&self) {
+        *self.window.lock() = None;
+    }
+
+    /// Messages waiting for a chat window
+    pub fn unread(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// Send a message from the end user; returns its ID
+    pub async fn send(&self, session_id: &str, body: &str) -> Result<String> {
+        let body = sanitize_body(body)?;
+        let message_id = Uuid::new_v4().to_string();
+        {
+            let mut sent = self.sent.lock();
+            if sent.len() >= TRACKED_MESSAGES {
+                sent.pop_front();
+            }
+            sent.push_back(TrackedMessage {
+                session_id: session_id.to_string(),
+                message_id: message_id.clone(),
+                status: ReceiptStatus::Sent,
+            });
+        }
+        let message = RelayMessage::ChatMessage {
+            session_id: session_id.to_string(),
+            message_id: message_id.clone(),
+            from: ChatParty::EndUser,
+            sender: Some(self.sender.clone()),
+            body,
+            sent_at: None,
+        };
+        self.outbox.send(message).await.context("Chat is shut down")?;
+        Ok(message_id)
+    }
+
+    /// How far one of the end user's messages got
+    pub fn status(&self, message_id: &str) -> Option<ReceiptStatus> {
+        self.sent.lock().iter().find(|tracked| tracked.message_id == message_id).map(|tracked| tracked.status)
+    }
+
+    /// Forget the chat of an ended session
+    pub fn end_session(&self, session_id: &str) {
+        self.pending.lock().retain(|message| message.session_id != session_id);
+        self.sent.lock().retain(|tracked| tracked.session_id != session_id);
+    }
+
+    async fn acknowledge(&self, message: &ChatMessage, status: ReceiptStatus) {
+        let receipt = RelayMessage::ChatReceipt {
+            session_id: message.session_id.clone(),
+            message_id: message.message_id.clone(),
+            status,
+        };
+        if self.outbox.send(receipt).await.is_err() {
+            debug!("Chat is shut down, dropping receipt for {}", message.message_id);
+        }
+    }
+}
+
+/// A reply left by `chat send` for the running agent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpooledMessage {
+    /// Unset sends to the agent's only session
+    #[serde(default)]
+    pub session_id: Option<String>,
+    pub body: String,
+}
+
+/// Directory `chat send` hands replies to the running agent through
+pub struct ChatSpool {
+    dir: PathBuf,
+}
+
+impl ChatSpool {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// `<config dir>/ghostlink/chat-outbox`
+    pub fn default_location() -> Self {
+        let dir = dirs::config_dir().unwrap_or_else(std::env::temp_dir);
+        Self::new(dir.join("ghostlink").join("chat-outbox"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Leave a reply for the agent
+    pub fn submit(&self, session_id: Option<String>, body: &str) -> Result<PathBuf> {
+        let message = SpooledMessage { session_id, body: sanitize_body(body)? };
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Failed to create {}", self.dir.display()))?;
+
+        // Named to sort in submission order; renamed into place so the agent
+        // never reads half a file
+        let name = format!("{:020}-{}", Utc::now().timestamp_micros(), Uuid::new_v4());
+        let partial = self.dir.join(format!("{}.tmp", name));
+        let path = self.dir.join(format!("{}.json", name));
+        std::fs::write(&partial, serde_json::to_vec(&message)?)
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        std::fs::rename(&partial, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Remove and return the replies waiting, oldest first; unreadable ones
+    /// are dropped
+    pub fn take(&self) -> Vec<SpooledMessage> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect();
+        paths.sort();
+
+        let mut messages = Vec::new();
+        for path in paths {
+            let contents = std::fs::read(&path);
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Failed to remove spooled chat message {}: {}", path.display(), e);
+                continue;
+            }
+            match contents.map_err(anyhow::Error::from).and_then(|contents| Ok(serde_json::from_slice(&contents)?)) {
+                Ok(message) => messages.push(message),
+                Err(e) => warn!("Dropping unreadable chat message {}: {}", path.display(), e),
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingWindow {
+        shown: Mutex<Vec<String>>,
+        receipts: Mutex<Vec<(String, ReceiptStatus)>>,
+    }
+
+    impl ChatWindow for RecordingWindow {
+        fn show(&self, message: &ChatMessage) {
+            self.shown.lock().push(message.body.clone());
+        }
+
+        fn receipt(&self, message_id: &str, status: ReceiptStatus) {
+            self.receipts.lock().push((message_id.to_string(), status));
+        }
+    }
+
+    fn from_technician(message_id: &str, body: &str) -> RelayMessage {
+        RelayMessage::ChatMessage {
+            session_id: "session-1".to_string(),
+            message_id: message_id.to_string(),
+            from: ChatParty::Technician,
+            sender: Some("Sam".to_string()),
+            body: body.to_string(),
+            sent_at: None,
+        }
+    }
+
+    fn receipt(message: RelayMessage) -> (String, ReceiptStatus) {
+        match message {
+            RelayMessage::ChatReceipt { message_id, status, .. } => (message_id, status),
+            other => panic!("expected a receipt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_receipts_only_move_forward() {
+        let mut status = ReceiptStatus::Sent;
+        assert!(status.advance(ReceiptStatus::Delivered));
+        assert!(!status.advance(ReceiptStatus::Sent));
+        assert!(status.advance(ReceiptStatus::Read));
+        assert!(!status.advance(ReceiptStatus::Delivered));
+        assert_eq!(status, ReceiptStatus::Read);
+    }
+
+    #[tokio::test]
+    async fn test_messages_wait_for_the_window() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let chat = ChatManager::new("alex".to_string(), tx);
+
+        chat.handle_message(from_technician("m1", "Hello")).await.unwrap();
+        chat.handle_message(from_technician("m2", "Are you there?")).await.unwrap();
+        assert_eq!(chat.unread(), 2);
+        assert_eq!(receipt(rx.recv().await.unwrap()), ("m1".to_string(), ReceiptStatus::Delivered));
+        assert_eq!(receipt(rx.recv().await.unwrap()), ("m2".to_string(), ReceiptStatus::Delivered));
+
+        let window = Arc::new(RecordingWindow::default());
+        chat.open_window(window.clone()).await;
+        assert_eq!(*window.shown.lock(), ["Hello", "Are you there?"]);
+        assert_eq!(chat.unread(), 0);
+        assert_eq!(receipt(rx.recv().await.unwrap()), ("m1".to_string(), ReceiptStatus::Read));
+        assert_eq!(receipt(rx.recv().await.unwrap()), ("m2".to_string(), ReceiptStatus::Read));
+
+        // With the window open messages are read straight away
+        chat.handle_message(from_technician("m3", "Thanks")).await.unwrap();
+        assert_eq!(window.shown.lock().last().unwrap(), "Thanks");
+        assert_eq!(receipt(rx.recv().await.unwrap()), ("m3".to_string(), ReceiptStatus::Read));
+    }
+
+    #[tokio::test]
+    async fn test_own_messages_follow_receipts() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let chat = ChatManager::new("alex".to_string(), tx);
+        let window = Arc::new(RecordingWindow::default());
+        chat.open_window(window.clone()).await;
+
+        let message_id = chat.send("session-1", "  it works now \u{7}").await.unwrap();
+        match rx.recv().await.unwrap() {
+            RelayMessage::ChatMessage { body, from, sender, .. } => {
+                assert_eq!((body.as_str(), from, sender.as_deref()), ("it works now", ChatParty::EndUser, Some("alex")));
+            }
+            other => panic!("expected the message, got {:?}", other),
+        }
+        assert!(chat.send("session-1", " \n ").await.is_err());
+
+        let update = |status| RelayMessage::ChatReceipt { session_id: "session-1".to_string(), message_id: message_id.clone(), status };
+        for status in [ReceiptStatus::Sent, ReceiptStatus::Read, ReceiptStatus::Delivered] {
+            chat.handle_message(update(status)).await.unwrap();
+        }
+        assert_eq!(chat.status(&message_id), Some(ReceiptStatus::Read));
+        assert_eq!(*window.receipts.lock(), [(message_id.clone(), ReceiptStatus::Read)]);
+
+        chat.end_session("session-1");
+        assert_eq!(chat.status(&message_id), None);
+    }
+
+    #[test]
+    fn test_spool_hands_over_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = ChatSpool::new(dir.path().join("outbox"));
+        assert!(spool.take().is_empty());
+
+        spool.submit(None, "first").unwrap();
+        spool.submit(Some("session-1".to_string()), "second").unwrap();
+        assert!(spool.submit(None, "").is_err());
+        std::fs::write(spool.dir().join("00000000000000000000-broken.json"), "{").unwrap();
+
+        let taken = spool.take();
+        assert_eq!(taken.iter().map(|message| message.body.as_str()).collect::<Vec<_>>(), ["first", "second"]);
+        assert_eq!(taken[1].session_id.as_deref(), Some("session-1"));
+        assert!(spool.take().is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Linux clipboard through wl-clipboard on Wayland or xclip on X11
+
+use anyhow::Result;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::info;
+
+use super::{ClipboardContent, PNG_CONTENT_TYPE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClipboardTool {
+    /// `wl-copy` / `wl-paste`
+    WlClipboard,
+    /// `xclip -selection clipboard`
+    Xclip,
+}
+
+pub struct LinuxClipboard {
+    tool: ClipboardTool,
+}
+
+impl LinuxClipboard {
+    /// Pick the tool for the running display server, if it is installed
+    pub fn detect() -> Option<Self> {
+        let candidates: &[(&str, &str, ClipboardTool)] = &[
+            ("WAYLAND_DISPLAY", "wl-paste", ClipboardTool::WlClipboard),
+            ("DISPLAY", "xclip", ClipboardTool::Xclip),
+        ];
+
+        for (display_var, program, tool) in candidates {
+            if std::env::var_os(display_var).is_none() {
+                continue;
+            }
+            let installed = std::process::Command::new("which")
+                .arg(program)
+                .output()
+                .map(|output| output.status.success())
+                .unwrap_or(false);
+            if installed {
+                info!("Using {} for clipboard synchronization", program);
+                return Some(Self { tool: *tool });
+            }
+        }
+
+        None
+    }
+
+    pub async fn read(&self) -> Result<Option<ClipboardContent>> {
+        let types = match self.tool {
+            ClipboardTool::WlClipboard => capture("wl-paste", &["--list-types"]).await?,
+            ClipboardTool::Xclip => capture("xclip", &["-selection", "clipboard", "-t", "TARGETS", "-o"]).await?,
+        };
+        let Some(types) = types else {
+            return Ok(None);
+        };
+        let types = String::from_utf8_lossy(&types);
+        let offers = |wanted: &str| types.lines().any(|line| line.trim() == wanted);
+
+        if offers(PNG_CONTENT_TYPE) {
+            let data = match self.tool {
+                ClipboardTool::WlClipboard => capture("wl-paste", &["--no-newline", "--type", PNG_CONTENT_TYPE]).await?,
+                ClipboardTool::Xclip => capture("xclip", &["-selection", "clipboard", "-t", PNG_CONTENT_TYPE, "-o"]).await?,
+            };
+            return Ok(data.map(ClipboardContent::Png));
+        }
+
+        let has_text = offers("text/plain;charset=utf-8") || offers("UTF8_STRING") || offers("text/plain") || offers("STRING");
+        if !has_text {
+            return Ok(None);
+        }
+
+        let data = match self.tool {
+            ClipboardTool::WlClipboard => capture("wl-paste", &["--no-newline", "--type", "text/plain;charset=utf-8"]).await?,
+            ClipboardTool::Xclip => capture("xclip", &["-selection", "clipboard", "-t", "UTF8_STRING", "-o"]).await?,
+        };
+        Ok(data.map(|data| ClipboardContent::Text(String::from_utf8_lossy(&data).into_owned())))
+    }
+
+    pub async fn write(&self, content: &ClipboardContent) -> Result<()> {
+        let (mime, data): (&str, &[u8]) = match content {
+            ClipboardContent::Text(text) => ("text/plain;charset=utf-8", text.as_bytes()),
+            ClipboardContent::Png(data) => (PNG_CONTENT_TYPE, data),
+        };
+
+        // Both tools fork a process that keeps serving the selection
+        match self.tool {
+            ClipboardTool::WlClipboard => feed("wl-copy", &["--type", mime], data).await,
+            ClipboardTool::Xclip => {
+                let target = if mime == PNG_CONTENT_TYPE { PNG_CONTENT_TYPE } else { "UTF8_STRING" };
+                feed("xclip", &["-selection", "clipboard", "-t", target, "-i"], data).await
+            }
+        }
+    }
+}
+
+/// Run a command and return its stdout, or `None` if it reports no selection
+async fn capture(program: &str, args: &[&str]) -> Result<Option<Vec<u8>>> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", program, e))?;
+
+    if output.status.success() {
+        Ok(Some(output.stdout))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Run a command with `data` on its stdin
+async fn feed(program: &str, args: &[&str], data: &[u8]) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", program, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(data).await?;
+    }
+
+    let status = child.wait().await?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("{} exited with {}", program, status))
+    }
+}
@@ -0,0 +1,50 @@
+//! macOS clipboard through the general NSPasteboard, via pbcopy and pbpaste
+
+use anyhow::Result;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use super::ClipboardContent;
+
+/// Text-only: pbcopy and pbpaste do not handle image data
+pub struct MacClipboard;
+
+impl MacClipboard {
+    pub async fn read(&self) -> Result<Option<ClipboardContent>> {
+        let output = Command::new("pbpaste")
+            .env("LANG", "en_US.UTF-8")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to run pbpaste: {}", e))?;
+
+        if !output.status.success() || output.stdout.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ClipboardContent::Text(String::from_utf8_lossy(&output.stdout).into_owned())))
+    }
+
+    pub async fn write(&self, content: &ClipboardContent) -> Result<()> {
+        let ClipboardContent::Text(text) = content else {
+            return Err(anyhow::anyhow!("Image clipboard content is not supported on macOS"));
+        };
+
+        let mut child = Command::new("pbcopy")
+            .env("LANG", "en_US.UTF-8")
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to run pbcopy: {}", e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).await?;
+        }
+
+        let status = child.wait().await?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("pbcopy exited with {}", status))
+        }
+    }
+}
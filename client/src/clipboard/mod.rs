@@ -0,0 +1,381 @@
+#![allow(dead_code)]
+
+use anyhow::Result;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::connection::RelayMessage;
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+
+#[cfg(target_os = "windows")]
+pub mod windows;
+
+#[cfg(target_os = "macos")]
+pub mod macos;
+
+pub const TEXT_CONTENT_TYPE: &str = "text/plain";
+pub const PNG_CONTENT_TYPE: &str = "image/png";
+
+/// Clipboard contents exchanged with the technician
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClipboardContent {
+    Text(String),
+    /// PNG-encoded image
+    Png(Vec<u8>),
+}
+
+impl ClipboardContent {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ClipboardContent::Text(_) => TEXT_CONTENT_TYPE,
+            ClipboardContent::Png(_) => PNG_CONTENT_TYPE,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ClipboardContent::Text(text) => text.len(),
+            ClipboardContent::Png(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Decode the payload of a `ClipboardSync` message; images travel as base64
+    pub fn from_message(content: &str, content_type: &str) -> Result<Self> {
+        match content_type {
+            TEXT_CONTENT_TYPE | "text" => Ok(ClipboardContent::Text(content.to_string())),
+            PNG_CONTENT_TYPE => {
+                let data = base64::engine::general_purpose::STANDARD.decode(content)
+                    .map_err(|e| anyhow::anyhow!("Invalid image clipboard payload: {}", e))?;
+                Ok(ClipboardContent::Png(data))
+            }
+            other => Err(anyhow::anyhow!("Unsupported clipboard content type: {}", other)),
+        }
+    }
+
+    /// Build the `ClipboardSync` message for a session
+    pub fn to_message(&self, session_id: &str) -> RelayMessage {
+        let content = match self {
+            ClipboardContent::Text(text) => text.clone(),
+            ClipboardContent::Png(data) => base64::engine::general_purpose::STANDARD.encode(data),
+        };
+
+        RelayMessage::ClipboardSync {
+            session_id: session_id.to_string(),
+            content,
+            content_type: self.content_type().to_string(),
+        }
+    }
+
+    /// Apply the size limit. Text is cut at a character boundary; an image
+    /// cannot be cut and is dropped instead.
+    pub fn limit(self, max_bytes: usize) -> Option<Self> {
+        if self.len() <= max_bytes {
+            return Some(self);
+        }
+
+        match self {
+            ClipboardContent::Text(mut text) => {
+                warn!("Clipboard text of {} bytes truncated to {} bytes", text.len(), max_bytes);
+                let mut end = max_bytes;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+                Some(ClipboardContent::Text(text))
+            }
+            ClipboardContent::Png(data) => {
+                warn!("Clipboard image of {} bytes exceeds the {} byte limit, not synchronized", data.len(), max_bytes);
+                None
+            }
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Clipboard synchronization policy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClipboardPolicy {
+    /// Master switch; when off the agent never reads or writes the clipboard
+    pub enabled: bool,
+    /// Largest clipboard payload exchanged, in bytes
+    pub max_bytes: usize,
+}
+
+impl Default for ClipboardPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Tracks what the local clipboard last held so remote writes are not echoed
+/// back and unchanged content is not sent twice
+#[derive(Debug, Default)]
+pub struct ClipboardState {
+    last_seen: Option<u64>,
+}
+
+impl ClipboardState {
+    /// Record content written on behalf of the technician.
+    /// Returns false if the clipboard already holds it.
+    pub fn record_remote(&mut self, content: &ClipboardContent) -> bool {
+        let fingerprint = content.fingerprint();
+        if self.last_seen == Some(fingerprint) {
+            return false;
+        }
+        self.last_seen = Some(fingerprint);
+        true
+    }
+
+    /// Record content read from the local clipboard.
+    /// Returns true if it is a local change that should be sent.
+    pub fn observe_local(&mut self, content: &ClipboardContent) -> bool {
+        let fingerprint = content.fingerprint();
+        match self.last_seen.replace(fingerprint) {
+            // The first read only establishes a baseline, so whatever was on
+            // the clipboard before the session started is not sent
+            None => false,
+            Some(previous) => previous != fingerprint,
+        }
+    }
+
+    /// Forget the baseline, e.g. when no session is synchronizing
+    pub fn reset(&mut self) {
+        self.last_seen = None;
+    }
+}
+
+/// Enum to hold the different clipboard implementations
+pub enum ClipboardBackendEnum {
+    #[cfg(target_os = "linux")]
+    Linux(linux::LinuxClipboard),
+    #[cfg(target_os = "windows")]
+    Windows(windows::WindowsClipboard),
+    #[cfg(target_os = "macos")]
+    Mac(macos::MacClipboard),
+    /// In-memory clipboard for headless agents and tests
+    Memory(MemoryClipboard),
+}
+
+impl ClipboardBackendEnum {
+    /// Read the clipboard; `None` if it is empty or holds an unsupported type
+    pub async fn read(&self) -> Result<Option<ClipboardContent>> {
+        match self {
+            #[cfg(target_os = "linux")]
+            ClipboardBackendEnum::Linux(backend) => backend.read().await,
+            #[cfg(target_os = "windows")]
+            ClipboardBackendEnum::Windows(backend) => backend.read().await,
+            #[cfg(target_os = "macos")]
+            ClipboardBackendEnum::Mac(backend) => backend.read().await,
+            ClipboardBackendEnum::Memory(backend) => backend.read().await,
+        }
+    }
+
+    /// Replace the clipboard contents
+    pub async fn write(&self, content: &ClipboardContent) -> Result<()> {
+        match self {
+            #[cfg(target_os = "linux")]
+            ClipboardBackendEnum::Linux(backend) => backend.write(content).await,
+            #[cfg(target_os = "windows")]
+            ClipboardBackendEnum::Windows(backend) => backend.write(content).await,
+            #[cfg(target_os = "macos")]
+            ClipboardBackendEnum::Mac(backend) => backend.write(content).await,
+            ClipboardBackendEnum::Memory(backend) => backend.write(content).await,
+        }
+    }
+}
+
+/// The clipboard of the machine the agent runs on, if it has one
+pub fn platform_backend() -> Option<ClipboardBackendEnum> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::LinuxClipboard::detect().map(ClipboardBackendEnum::Linux)
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        Some(ClipboardBackendEnum::Windows(windows::WindowsClipboard))
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        Some(ClipboardBackendEnum::Mac(macos::MacClipboard))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+    {
+        None
+    }
+}
+
+/// Clipboard held in memory
+#[derive(Default)]
+pub struct MemoryClipboard {
+    content: std::sync::Mutex<Option<ClipboardContent>>,
+}
+
+impl MemoryClipboard {
+    pub async fn read(&self) -> Result<Option<ClipboardContent>> {
+        Ok(self.content.lock().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+
+    pub async fn write(&self, content: &ClipboardContent) -> Result<()> {
+        *self.content.lock().unwrap_or_else(|e| e.into_inner()) = Some(content.clone());
+        Ok(())
+    }
+}
+
+/// Backend and loop-prevention state, locked together so a poll can never
+/// interleave with a remote write
+struct ClipboardInner {
+    backend: ClipboardBackendEnum,
+    state: ClipboardState,
+}
+
+/// Synchronizes the local clipboard with the technician's
+pub struct ClipboardService {
+    inner: Mutex<ClipboardInner>,
+    max_bytes: usize,
+}
+
+impl ClipboardService {
+    pub fn new(backend: ClipboardBackendEnum, policy: &ClipboardPolicy) -> Self {
+        Self {
+            inner: Mutex::new(ClipboardInner {
+                backend,
+                state: ClipboardState::default(),
+            }),
+            max_bytes: policy.max_bytes,
+        }
+    }
+
+    /// Set the clipboard from a `ClipboardSync` payload.
+    /// Returns false if the clipboard already held the content.
+    pub async fn apply_remote(&self, content: &str, content_type: &str) -> Result<bool> {
+        let Some(content) = ClipboardContent::from_message(content, content_type)?.limit(self.max_bytes) else {
+            return Ok(false);
+        };
+
+        let mut inner = self.inner.lock().await;
+        if !inner.state.record_remote(&content) {
+            debug!("Clipboard already holds the remote content");
+            return Ok(false);
+        }
+        if let Err(e) = inner.backend.write(&content).await {
+            inner.state.reset();
+            return Err(e);
+        }
+
+        debug!("Clipboard set from remote ({}, {} bytes)", content.content_type(), content.len());
+        Ok(true)
+    }
+
+    /// Read the local clipboard and return its content if the user changed it
+    pub async fn poll_local_change(&self) -> Result<Option<ClipboardContent>> {
+        let mut inner = self.inner.lock().await;
+        let Some(content) = inner.backend.read().await? else {
+            return Ok(None);
+        };
+        let Some(content) = content.limit(self.max_bytes) else {
+            return Ok(None);
+        };
+
+        if inner.state.observe_local(&content) {
+            Ok(Some(content))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Forget what the clipboard held; the next poll starts a new baseline
+    pub async fn reset(&self) {
+        self.inner.lock().await.state.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_service() -> ClipboardService {
+        ClipboardService::new(ClipboardBackendEnum::Memory(MemoryClipboard::default()), &ClipboardPolicy::default())
+    }
+
+    async fn set_local(service: &ClipboardService, content: ClipboardContent) {
+        service.inner.lock().await.backend.write(&content).await.unwrap();
+    }
+
+    #[test]
+    fn test_loop_prevention() {
+        let mut state = ClipboardState::default();
+        let before = ClipboardContent::Text("before session".to_string());
+        let remote = ClipboardContent::Text("from technician".to_string());
+        let local = ClipboardContent::Text("copied by user".to_string());
+
+        // Existing content is only a baseline
+        assert!(!state.observe_local(&before));
+
+        // A remote write is not echoed back when the poll reads it
+        assert!(state.record_remote(&remote));
+        assert!(!state.observe_local(&remote));
+
+        assert!(state.observe_local(&local));
+        assert!(!state.observe_local(&local));
+
+        // The technician receiving our copy and sending it back is a no-op
+        assert!(!state.record_remote(&local));
+
+        // Copying the remote content again after something else is a real change
+        assert!(state.observe_local(&remote));
+    }
+
+    #[tokio::test]
+    async fn test_message_round_trip() {
+        let service = memory_service();
+        assert_eq!(service.poll_local_change().await.unwrap(), None);
+
+        let message = ClipboardContent::Text("hostname: srv01".to_string()).to_message("session-1");
+        let json = serde_json::to_string(&message).unwrap();
+        let RelayMessage::ClipboardSync { content, content_type, .. } = serde_json::from_str(&json).unwrap() else {
+            panic!("expected ClipboardSync");
+        };
+        assert!(service.apply_remote(&content, &content_type).await.unwrap());
+        assert!(!service.apply_remote(&content, &content_type).await.unwrap());
+        assert_eq!(service.poll_local_change().await.unwrap(), None);
+
+        let image = ClipboardContent::Png(vec![0x89, b'P', b'N', b'G', 0, 1, 2]);
+        set_local(&service, image.clone()).await;
+        let changed = service.poll_local_change().await.unwrap().unwrap();
+        assert_eq!(changed, image);
+
+        let RelayMessage::ClipboardSync { session_id, content, content_type } = changed.to_message("session-1") else {
+            panic!("expected ClipboardSync");
+        };
+        assert_eq!((session_id.as_str(), content_type.as_str()), ("session-1", PNG_CONTENT_TYPE));
+        assert_eq!(ClipboardContent::from_message(&content, &content_type).unwrap(), image);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let text = ClipboardContent::Text("héllo".to_string());
+        assert_eq!(text.limit(2), Some(ClipboardContent::Text("h".to_string())));
+        assert_eq!(ClipboardContent::Png(vec![0; 10]).limit(5), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Win32 clipboard backend
+
+use anyhow::Result;
+use ::windows::core::w;
+use ::windows::Win32::Foundation::{GlobalFree, HANDLE, HGLOBAL, HWND};
+use ::windows::Win32::System::DataExchange::{
+    CloseClipboard, EmptyClipboard, GetClipboardData, IsClipboardFormatAvailable, OpenClipboard,
+    RegisterClipboardFormatW, SetClipboardData,
+};
+use ::windows::Win32::System::Memory::{GlobalAlloc, GlobalLock, GlobalSize, GlobalUnlock, GMEM_MOVEABLE};
+
+use super::ClipboardContent;
+
+/// Standard clipboard format for UTF-16 text
+const CF_UNICODETEXT: u32 = 13;
+
+/// Attempts to open the clipboard while another application holds it
+const OPEN_ATTEMPTS: u32 = 5;
+
+/// Clipboard of the interactive desktop
+pub struct WindowsClipboard;
+
+impl WindowsClipboard {
+    pub async fn read(&self) -> Result<Option<ClipboardContent>> {
+        tokio::task::spawn_blocking(read_clipboard).await?
+    }
+
+    pub async fn write(&self, content: &ClipboardContent) -> Result<()> {
+        let content = content.clone();
+        tokio::task::spawn_blocking(move || write_clipboard(&content)).await?
+    }
+}
+
+/// Open clipboard, closed on drop
+struct OpenedClipboard;
+
+impl OpenedClipboard {
+    fn open() -> Result<Self> {
+        for attempt in 0..OPEN_ATTEMPTS {
+            if unsafe { OpenClipboard(HWND(0)) }.is_ok() {
+                return Ok(Self);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10 << attempt));
+        }
+        Err(anyhow::anyhow!("Clipboard is in use by another application"))
+    }
+}
+
+impl Drop for OpenedClipboard {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseClipboard();
+        }
+    }
+}
+
+/// Format id of the "PNG" format most applications publish images in
+fn png_format() -> u32 {
+    unsafe { RegisterClipboardFormatW(w!("PNG")) }
+}
+
+/// Copy the bytes behind a clipboard handle
+fn read_global(handle: HANDLE) -> Option<Vec<u8>> {
+    let global = HGLOBAL(handle.0 as *mut core::ffi::c_void);
+    unsafe {
+        let size = GlobalSize(global);
+        let ptr = GlobalLock(global) as *const u8;
+        if ptr.is_null() {
+            return None;
+        }
+        let data = std::slice::from_raw_parts(ptr, size).to_vec();
+        let _ = GlobalUnlock(global);
+        Some(data)
+    }
+}
+
+fn read_clipboard() -> Result<Option<ClipboardContent>> {
+    let _clipboard = OpenedClipboard::open()?;
+    let png = png_format();
+
+    unsafe {
+        if png != 0 && IsClipboardFormatAvailable(png).is_ok() {
+            if let Some(data) = GetClipboardData(png).ok().and_then(read_global) {
+                return Ok(Some(ClipboardContent::Png(data)));
+            }
+        }
+
+        if IsClipboardFormatAvailable(CF_UNICODETEXT).is_ok() {
+            if let Some(data) = GetClipboardData(CF_UNICODETEXT).ok().and_then(read_global) {
+                let wide: Vec<u16> = data.chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .take_while(|&unit| unit != 0)
+                    .collect();
+                return Ok(Some(ClipboardContent::Text(String::from_utf16_lossy(&wide))));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn write_clipboard(content: &ClipboardContent) -> Result<()> {
+    let (format, bytes) = match content {
+        ClipboardContent::Text(text) => {
+            let bytes: Vec<u8> = text.encode_utf16()
+                .chain(std::iter::once(0))
+                .flat_map(|unit| unit.to_le_bytes())
+                .collect();
+            (CF_UNICODETEXT, bytes)
+        }
+        ClipboardContent::Png(data) => (png_format(), data.clone()),
+    };
+    if format == 0 {
+        return Err(anyhow::anyhow!("PNG clipboard format is unavailable"));
+    }
+
+    let _clipboard = OpenedClipboard::open()?;
+    unsafe {
+        EmptyClipboard()?;
+
+        let global = GlobalAlloc(GMEM_MOVEABLE, bytes.len())?;
+        let ptr = GlobalLock(global) as *mut u8;
+        if ptr.is_null() {
+            let _ = GlobalFree(global);
+            return Err(anyhow::anyhow!("Failed to lock clipboard memory"));
+        }
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+        let _ = GlobalUnlock(global);
+
+        // On success the clipboard owns the memory
+        if let Err(e) = SetClipboardData(format, HANDLE(global.0 as isize)) {
+            let _ = GlobalFree(global);
+            return Err(e.into());
+        }
+    }
+
+    Ok(())
+}
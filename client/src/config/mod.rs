@@ -0,0 +1,626 @@
+//! Agent configuration
+//!
+//! The agent reads `client.toml` from the platform config directory, or the
+//! file `--config` or `GHOSTLINK_CONFIG` names; a `.json` file holds the
+//! same keys as JSON. Values are layered: command-line flags over the
+//! `GHOSTLINK_*` variables in [`ENV_OVERRIDES`] over the file over the
+//! defaults. Unknown keys and values out of range fail the load with the
+//! line and column they sit on, and `config validate` runs the same checks
+//! without starting the agent. A running agent picks up changes to the
+//! values in [`reload::LiveSettings`].
+
+#![allow(dead_code)]
+
+pub mod reload;
+pub mod validate;
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+use crate::agent::inventory::InventoryPolicy;
+use crate::capture::encoder_profile::EncodingPolicy;
+use crate::capture::CaptureConfig;
+use crate::clipboard::ClipboardPolicy;
+use crate::connection::compression::CompressionPolicy;
+use crate::connection::enrollment::DeviceCredential;
+use crate::error::{ConfigError, GhostLinkError, Result};
+use crate::file_transfer::FileTransferPolicy;
+use crate::logging::crash::CrashReportPolicy;
+use crate::logging::{self, LoggingConfig};
+use crate::recording::RecordingConfig;
+use crate::registry::RegistryPolicy;
+use crate::resources::ResourcePolicy;
+use crate::session::input_indicator::IndicatorPolicy;
+use crate::session::watchdog::WatchdogPolicy;
+use crate::session::{AdhocPolicy, IdlePolicy};
+use crate::terminal::TerminalPolicy;
+use crate::toolbox::ToolboxConfig;
+use crate::updater::UpdatePolicy;
+
+/// Relay the agent connects to when neither flag, environment nor file
+/// names one
+pub const DEFAULT_SERVER_URL: &str = "wss://relay.cktechx.com";
+
+/// Environment variable naming the config file, as `--config` does
+pub const PATH_ENV: &str = "GHOSTLINK_CONFIG";
+
+/// Environment variables that set config values, and the key each sets
+pub const ENV_OVERRIDES: [(&str, &str); 9] = [
+    ("GHOSTLINK_SERVER", "server_url"),
+    (logging::LEVEL_ENV, "log_level"),
+    ("GHOSTLINK_HEARTBEAT_INTERVAL", "heartbeat_interval"),
+    ("GHOSTLINK_RECONNECT_INTERVAL", "reconnect_interval"),
+    ("GHOSTLINK_MAX_FPS", "encoding.adaptive.max_fps"),
+    ("GHOSTLINK_LOG_DIR", "logging.dir"),
+    ("GHOSTLINK_TOOLS_DIR", "toolbox.local_tools_path"),
+    ("GHOSTLINK_USE_PORTAL", "capture.use_portal"),
+    ("GHOSTLINK_FAST_CAPTURE", "capture.fast_capture"),
+];
+
+/// Config file `--config` picked, set before anything loads one
+static PATH_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Generated on first start and kept from then on
+    pub agent_id: String,
+    pub hostname: String,
+    #[serde(deserialize_with = "validate::server_url")]
+    pub server_url: String,
+    #[serde(deserialize_with = "validate::interval")]
+    pub reconnect_interval: u64,
+    /// Seconds between heartbeats; a config reload changes it for the
+    /// running agent, unless the server's policy sets one
+    #[serde(deserialize_with = "validate::interval")]
+    pub heartbeat_interval: u64,
+    #[serde(deserialize_with = "validate::session_limit")]
+    pub max_concurrent_sessions: u32,
+    /// Log level or filter directives; `--log-level` and `GHOSTLINK_LOG`
+    /// take precedence
+    #[serde(deserialize_with = "validate::log_level")]
+    pub log_level: String,
+    /// PEM file of CA certificates trusted for the relay on top of the
+    /// system's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_bundle_path: Option<PathBuf>,
+    /// SHA-256 hashes (hex or base64) of the relay certificate's public
+    /// key; when set, the relay must present one of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_cert_sha256: Vec<String>,
+    /// Skip certificate validation, for labs with throwaway certificates;
+    /// pins are still checked
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+    /// Proxy for the relay and every HTTP call, as `http://host:port` or
+    /// `socks5://host:port`; without one, `https_proxy`, `http_proxy`,
+    /// `all_proxy` and `no_proxy` decide
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "validate::proxy_url")]
+    pub proxy_url: Option<String>,
+    /// Proxy credentials, taking precedence over any in the proxy URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_password: Option<String>,
+    /// On Windows, use the WinHTTP proxy when neither `proxy_url` nor the
+    /// environment names one
+    #[serde(default)]
+    pub system_proxy: bool,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub file_transfer: FileTransferPolicy,
+    #[serde(default)]
+    pub registry: RegistryPolicy,
+    #[serde(default)]
+    pub encoding: EncodingPolicy,
+    #[serde(default)]
+    pub clipboard: ClipboardPolicy,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    #[serde(default)]
+    pub update: UpdatePolicy,
+    #[serde(default)]
+    pub terminal: TerminalPolicy,
+    #[serde(default)]
+    pub adhoc: AdhocPolicy,
+    #[serde(default)]
+    pub idle: IdlePolicy,
+    #[serde(default)]
+    pub watchdog: WatchdogPolicy,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub toolbox: ToolboxConfig,
+    /// When JSON to the relay is compressed, once it agrees to a codec
+    #[serde(default)]
+    pub compression: CompressionPolicy,
+    #[serde(default)]
+    pub inventory: InventoryPolicy,
+    /// Corner window naming the technician while their input reaches the
+    /// machine
+    #[serde(default)]
+    pub input_indicator: IndicatorPolicy,
+    /// Whether crash reports are uploaded, absent a policy saying
+    #[serde(default)]
+    pub crash_reports: CrashReportPolicy,
+    /// CPU, memory and priority limits for capture and recording
+    #[serde(default)]
+    pub resources: ResourcePolicy,
+    /// Signing key from enrollment; unset until the agent is enrolled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<DeviceCredential>,
+    /// Support code `join` registers with; never saved, since a joined
+    /// agent is temporary
+    #[serde(skip)]
+    pub support_code: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            agent_id: String::new(),
+            hostname: default_hostname(),
+            server_url: DEFAULT_SERVER_URL.to_string(),
+            reconnect_interval: 30, // seconds
+            heartbeat_interval: 30, // seconds
+            max_concurrent_sessions: 5,
+            log_level: "info".to_string(),
+            ca_bundle_path: None,
+            pinned_cert_sha256: Vec::new(),
+            danger_accept_invalid_certs: false,
+            proxy_url: None,
+            proxy_username: None,
+            proxy_password: None,
+            system_proxy: false,
+            logging: LoggingConfig::default(),
+            file_transfer: FileTransferPolicy::default(),
+            registry: RegistryPolicy::default(),
+            encoding: EncodingPolicy::default(),
+            clipboard: ClipboardPolicy::default(),
+            recording: RecordingConfig::default(),
+            update: UpdatePolicy::default(),
+            terminal: TerminalPolicy::default(),
+            adhoc: AdhocPolicy::default(),
+            idle: IdlePolicy::default(),
+            watchdog: WatchdogPolicy::default(),
+            capture: CaptureConfig::default(),
+            toolbox: ToolboxConfig::default(),
+            compression: CompressionPolicy::default(),
+            inventory: InventoryPolicy::default(),
+            input_indicator: IndicatorPolicy::default(),
+            crash_reports: CrashReportPolicy::default(),
+            resources: ResourcePolicy::default(),
+            credential: None,
+            support_code: None,
+        }
+    }
+}
+
+
+impl ClientConfig {
+    pub fn new(server_url: String, device_name: Option<String>) -> Result<Self> {
+        Ok(ClientConfig {
+            agent_id: Self::get_or_create_device_id()?,
+            hostname: device_name.unwrap_or_else(default_hostname),
+            server_url,
+            ..Self::default()
+        })
+    }
+
+    /// Read and write the config at `path` instead of the default one;
+    /// only the first call counts
+    pub fn use_path(path: PathBuf) {
+        let _ = PATH_OVERRIDE.set(path);
+    }
+
+    /// Where the agent keeps its config, including the enrolled identity:
+    /// the `--config` file, else `GHOSTLINK_CONFIG`, else `client.toml` in
+    /// the platform config directory. Policy, queue and update state are
+    /// kept next to it.
+    pub fn default_path() -> PathBuf {
+        if let Some(path) = PATH_OVERRIDE.get() {
+            return path.clone();
+        }
+        if let Some(path) = env::var_os(PATH_ENV).filter(|path| !path.is_empty()) {
+            return PathBuf::from(path);
+        }
+        dirs::config_dir()
+            .unwrap_or_else(env::temp_dir)
+            .join("ghostlink")
+            .join("client.toml")
+    }
+    
+    /// The config at `path`, with a fresh agent id if the file has none;
+    /// a missing file is created with the defaults
+    pub fn load(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let mut config = Self::read(path)?;
+            if config.agent_id.is_empty() {
+                config.agent_id = Self::get_or_create_device_id()?;
+            }
+            Ok(config)
+        } else {
+            // Create default config
+            let config = Self::new(DEFAULT_SERVER_URL.to_string(), None)?;
+            config.save(path)?;
+            Ok(config)
+        }
+    }
+
+    /// Read the file at `path` as it is, without the environment's values
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => ConfigError::FileNotFound { path: path.display().to_string() }.into(),
+            _ => GhostLinkError::from(e).context(path.display()),
+        })?;
+        Self::parse(&content, path)
+    }
+
+    /// Parse the content of the config file at `path`, as JSON if its name
+    /// ends in `.json` and as TOML otherwise
+    pub fn parse(content: &str, path: &Path) -> Result<Self> {
+        let invalid = |line: usize, column: usize, reason: &str| -> GhostLinkError {
+            ConfigError::Invalid { path: path.display().to_string(), line, column, reason: reason.to_string() }.into()
+        };
+        if is_json(path) {
+            serde_json::from_str(content).map_err(|e| {
+                // The position is reported on its own
+                let message = e.to_string();
+                let position = format!(" at line {} column {}", e.line(), e.column());
+                invalid(e.line(), e.column(), message.strip_suffix(&position).unwrap_or(&message))
+            })
+        } else {
+            toml::from_str(content).map_err(|e| {
+                let (line, column) = e.span().map_or((1, 1), |span| line_and_column(content, span.start));
+                invalid(line, column, e.message())
+            })
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let content = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self).map_err(|e| ConfigError::InvalidFormat { reason: e.to_string() })?
+        };
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Take the values the variables in [`ENV_OVERRIDES`] set, which beat
+    /// the file's
+    pub fn apply_env(&mut self) -> Result<()> {
+        self.apply_vars(|name| env::var(name).ok().filter(|value| !value.trim().is_empty()))
+    }
+
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(value) = var("GHOSTLINK_SERVER") {
+            self.server_url = env_value("GHOSTLINK_SERVER", &value, |url: &String| validate::check_server_url(url))?;
+        }
+        if let Some(value) = var(logging::LEVEL_ENV) {
+            self.log_level = env_value(logging::LEVEL_ENV, &value, |level: &String| validate::check_log_level(level))?;
+        }
+        if let Some(value) = var("GHOSTLINK_HEARTBEAT_INTERVAL") {
+            self.heartbeat_interval = env_value("GHOSTLINK_HEARTBEAT_INTERVAL", &value, validate::check_interval)?;
+        }
+        if let Some(value) = var("GHOSTLINK_RECONNECT_INTERVAL") {
+            self.reconnect_interval = env_value("GHOSTLINK_RECONNECT_INTERVAL", &value, validate::check_interval)?;
+        }
+        if let Some(value) = var("GHOSTLINK_MAX_FPS") {
+            self.encoding.adaptive.max_fps = env_value("GHOSTLINK_MAX_FPS", &value, validate::check_fps)?;
+        }
+        if let Some(value) = var("GHOSTLINK_LOG_DIR") {
+            self.logging.dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("GHOSTLINK_TOOLS_DIR") {
+            self.toolbox.local_tools_path = PathBuf::from(value);
+        }
+        if let Some(value) = var("GHOSTLINK_USE_PORTAL") {
+            self.capture.use_portal = env_flag("GHOSTLINK_USE_PORTAL", &value)?;
+        }
+        if let Some(value) = var("GHOSTLINK_FAST_CAPTURE") {
+            self.capture.fast_capture = env_flag("GHOSTLINK_FAST_CAPTURE", &value)?;
+        }
+        Ok(())
+    }
+
+    /// Check the values flags and the environment may have set since the
+    /// file was parsed, and those that depend on each other
+    pub fn validate(&self) -> Result<()> {
+        let check = |field: &str, outcome: std::result::Result<(), String>| {
+            outcome.map_err(|reason| GhostLinkError::from(ConfigError::InvalidValue { field: field.to_string(), reason }))
+        };
+        check("server_url", validate::check_server_url(&self.server_url))?;
+        check("reconnect_interval", validate::check_interval(&self.reconnect_interval))?;
+        check("heartbeat_interval", validate::check_interval(&self.heartbeat_interval))?;
+        check("max_concurrent_sessions", validate::check_session_limit(&self.max_concurrent_sessions))?;
+        check("log_level", validate::check_log_level(&self.log_level))?;
+        if let Some(url) = &self.proxy_url {
+            check("proxy_url", validate::check_proxy_url(url))?;
+        }
+        if self.proxy_password.is_some() && self.proxy_username.is_none() {
+            check("proxy_password", Err("is set without proxy_username".to_string()))?;
+        }
+
+        let adaptive = &self.encoding.adaptive;
+        check("encoding.adaptive.max_fps", validate::check_fps(&adaptive.max_fps))?;
+        if adaptive.min_fps > adaptive.max_fps {
+            let reason = format!("{} is above max_fps ({})", adaptive.min_fps, adaptive.max_fps);
+            check("encoding.adaptive.min_fps", Err(reason))?;
+        }
+        if let Some(percent) = &self.resources.cpu_budget_percent {
+            check("resources.cpu_budget_percent", validate::check_cpu_budget(percent))?;
+        }
+        if let Some(mb) = &self.resources.memory_limit_mb {
+            check("resources.memory_limit_mb", validate::check_memory_limit(mb))?;
+        }
+        Ok(())
+    }
+    
+    fn get_or_create_device_id() -> Result<String> {
+        // In a real implementation, this would:
+        // 1. Check for existing device ID in registry/config file
+        // 2. Generate new one if not found
+        // 3. Store it persistently
+        
+        // For now, generate a new UUID each time
+        // TODO: Implement persistent storage
+        Ok(Uuid::new_v4().to_string())
+    }
+}
+
+/// Values given on the command line, which beat every other layer
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub server_url: Option<String>,
+    pub hostname: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut ClientConfig) {
+        if let Some(url) = &self.server_url {
+            config.server_url = url.clone();
+        }
+        if let Some(hostname) = &self.hostname {
+            config.hostname = hostname.clone();
+        }
+    }
+}
+
+fn default_hostname() -> String {
+    env::var("COMPUTERNAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "Unknown Device".to_string())
+}
+
+/// Whether the config at `path` is JSON rather than TOML
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+/// 1-based line and column of byte `offset` in `content`
+fn line_and_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = content.get(..offset).unwrap_or(content);
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |line| line.chars().count()) + 1;
+    (line, column)
+}
+
+/// The value of environment variable `name`, parsed and checked
+fn env_value<T>(name: &str, value: &str, check: impl Fn(&T) -> std::result::Result<(), String>) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let invalid = |reason: String| GhostLinkError::from(ConfigError::InvalidValue { field: name.to_string(), reason });
+    let parsed = value.trim().parse::<T>().map_err(|e| invalid(format!("{:?}: {}", value, e)))?;
+    check(&parsed).map_err(invalid)?;
+    Ok(parsed)
+}
+
+/// An on/off environment variable, as 1, true, yes or on and their opposites
+fn env_flag(name: &str, value: &str) -> Result<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError::InvalidValue {
+            field: name.to_string(),
+            reason: format!("{:?} is neither 1, true, yes, on nor 0, false, no, off", value),
+        }.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_new_config() {
+        let config = ClientConfig::new(
+            "wss://test.example.com".to_string(),
+            Some("Test Device".to_string()),
+        ).unwrap();
+        
+        assert_eq!(config.server_url, "wss://test.example.com");
+        assert_eq!(config.hostname, "Test Device");
+        assert_eq!(config.reconnect_interval, 30);
+        assert_eq!(config.heartbeat_interval, 30);
+        assert_eq!(config.max_concurrent_sessions, 5);
+        assert_eq!(config.log_level, "info");
+        assert!(!config.agent_id.is_empty());
+    }
+
+    #[test]
+    fn test_new_config_default_hostname() {
+        let config = ClientConfig::new(
+            "wss://test.example.com".to_string(),
+            None,
+        ).unwrap();
+        
+        assert_eq!(config.server_url, "wss://test.example.com");
+        assert!(!config.hostname.is_empty());
+    }
+
+    #[test]
+    fn test_config_save_and_load() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let temp_path = temp_file.path();
+        
+        // Create and save config
+        let original_config = ClientConfig::new(
+            "wss://save-test.example.com".to_string(),
+            Some("Save Test Device".to_string()),
+        ).unwrap();
+        
+        original_config.save(temp_path).unwrap();
+        
+        // Load config
+        let loaded_config = ClientConfig::load(temp_path).unwrap();
+        
+        assert_eq!(original_config.server_url, loaded_config.server_url);
+        assert_eq!(original_config.hostname, loaded_config.hostname);
+        assert_eq!(original_config.agent_id, loaded_config.agent_id);
+        assert!(loaded_config.credential.is_none());
+    }
+
+    #[test]
+    fn test_flags_beat_environment_beats_file_beats_defaults() {
+        let path = Path::new("client.toml");
+        let content = "server_url = \"wss://file.example.com\"\nheartbeat_interval = 10\nreconnect_interval = 20\n";
+        let mut config = ClientConfig::parse(content, path).unwrap();
+        assert_eq!(config.max_concurrent_sessions, 5);
+        assert_eq!((config.heartbeat_interval, config.reconnect_interval), (10, 20));
+
+        let vars = HashMap::from([
+            ("GHOSTLINK_SERVER", "wss://env.example.com"),
+            ("GHOSTLINK_HEARTBEAT_INTERVAL", "15"),
+            ("GHOSTLINK_USE_PORTAL", "off"),
+        ]);
+        config.apply_vars(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!(config.server_url, "wss://env.example.com");
+        assert_eq!((config.heartbeat_interval, config.reconnect_interval), (15, 20));
+        assert!(!config.capture.use_portal);
+        assert!(config.capture.fast_capture);
+
+        let flags = Overrides { server_url: Some("wss://flag.example.com".to_string()), hostname: None };
+        flags.apply(&mut config);
+        assert_eq!(config.server_url, "wss://flag.example.com");
+        config.validate().unwrap();
+    }
+
+    /// Line, column and reason of a config that fails to parse
+    fn parse_error(content: &str, file_name: &str) -> (usize, usize, String) {
+        match ClientConfig::parse(content, Path::new(file_name)) {
+            Err(GhostLinkError::Config(ConfigError::Invalid { line, column, reason, .. })) => (line, column, reason),
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_validation_errors_name_the_line() {
+        let (line, column, reason) = parse_error("server_url = \"wss://relay\"\n\nheartbeat_interval = 0\n", "client.toml");
+        assert_eq!((line, column), (3, 22));
+        assert!(reason.contains("between 1 and 86400"), "{}", reason);
+
+        let (line, _, reason) = parse_error("log_level = \"info\"\nheartbeet_interval = 5\n", "client.toml");
+        assert_eq!(line, 2);
+        assert!(reason.contains("unknown field `heartbeet_interval`"), "{}", reason);
+
+        let (line, _, reason) = parse_error("server_url = \"https://relay.example.com\"\n", "client.toml");
+        assert_eq!(line, 1);
+        assert!(reason.contains("ws:// or wss://"), "{}", reason);
+
+        let (line, _, reason) = parse_error("[encoding.adaptive]\nmin_fps = 5\nmax_fps = 500\n", "client.toml");
+        assert_eq!(line, 3);
+        assert!(reason.contains("frames per second"), "{}", reason);
+
+        let (line, _, reason) = parse_error("log_level = \"info\"\nproxy_url = \"https://proxy.corp.example\"\n", "client.toml");
+        assert_eq!(line, 2);
+        assert!(reason.contains("http:// proxy"), "{}", reason);
+
+        let (line, _, reason) = parse_error("{\n  \"max_concurrent_sessions\": 0,\n  \"log_level\": \"info\"\n}", "client.json");
+        assert_eq!(line, 2);
+        assert!(reason.contains("at least 1"), "{}", reason);
+        assert!(!reason.contains("at line"), "{}", reason);
+    }
+
+    #[test]
+    fn test_ipv6_server_urls() {
+        assert!(validate::check_server_url("wss://[2001:db8::7]:8443/relay/ws").is_ok());
+        assert!(validate::check_server_url("ws://[::1]").is_ok());
+
+        let reason = validate::check_server_url("wss://2001:db8::7/relay/ws").unwrap_err();
+        assert!(reason.contains("\"wss://[2001:db8::7]/relay/ws\""), "{}", reason);
+        let reason = validate::check_server_url("wss://relay:port").unwrap_err();
+        assert!(!reason.contains("brackets"), "{}", reason);
+
+        let mut config = ClientConfig::default();
+        Overrides { server_url: Some("wss://fd00::7".to_string()), hostname: None }.apply(&mut config);
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("brackets"), "{}", err);
+    }
+
+    #[test]
+    fn test_environment_and_cross_field_errors() {
+        let mut config = ClientConfig::default();
+        let err = config.apply_vars(|name| (name == "GHOSTLINK_MAX_FPS").then(|| "fast".to_string())).unwrap_err();
+        assert!(err.to_string().contains("GHOSTLINK_MAX_FPS"), "{}", err);
+        let err = config.apply_vars(|name| (name == "GHOSTLINK_FAST_CAPTURE").then(|| "maybe".to_string())).unwrap_err();
+        assert!(err.to_string().contains("GHOSTLINK_FAST_CAPTURE"), "{}", err);
+        assert_eq!(err.exit_code(), 78);
+
+        config.encoding.adaptive.min_fps = 30;
+        config.encoding.adaptive.max_fps = 20;
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("encoding.adaptive.min_fps"), "{}", err);
+
+        config.encoding.adaptive.min_fps = 10;
+        config.proxy_password = Some("secret".to_string());
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("proxy_username"), "{}", err);
+    }
+
+    #[test]
+    fn test_json_config_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let mut config = ClientConfig::new("wss://relay.example.com/relay/ws".to_string(), Some("kiosk".to_string())).unwrap();
+        config.heartbeat_interval = 45;
+        config.save(&path).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().trim_start().starts_with('{'));
+
+        let loaded = ClientConfig::load(&path).unwrap();
+        assert_eq!(loaded.hostname, "kiosk");
+        assert_eq!(loaded.heartbeat_interval, 45);
+        assert_eq!(loaded.agent_id, config.agent_id);
+    }
+
+    #[test]
+    fn test_credential_survives_save_and_load() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let mut config = ClientConfig::new(
+            "wss://save-test.example.com/relay/ws".to_string(),
+            None,
+        ).unwrap();
+        config.credential = Some(DeviceCredential::generate().unwrap());
+        config.save(temp_file.path()).unwrap();
+
+        let loaded = ClientConfig::load(temp_file.path()).unwrap();
+        let credential = loaded.credential.unwrap();
+        assert_eq!(
+            credential.public_key_base64().unwrap(),
+            config.credential.unwrap().public_key_base64().unwrap()
+        );
+    }
+}
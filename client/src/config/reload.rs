@@ -0,0 +1,211 @@
+//! Config changes applied to a running agent
+//!
+//! [`watch`] reads the config file again every couple of seconds. Once it
+//! changed and still validates, the [`LiveSettings`] go to the agent: the
+//! heartbeat timer restarts with the new interval, running sessions take
+//! the new frame rate cap, and [`follow_log_level`] swaps the log filter.
+//! Other values that changed wait for a restart, which is logged, and a
+//! file that no longer validates is logged and otherwise ignored.
+
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use super::ClientConfig;
+use crate::logging;
+
+/// How often the config file is checked for changes
+pub const RELOAD_POLL: Duration = Duration::from_secs(2);
+
+/// Values that take effect without restarting the agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveSettings {
+    pub log_level: String,
+    pub heartbeat_interval: u64,
+    pub max_fps: u32,
+}
+
+impl LiveSettings {
+    pub fn of(config: &ClientConfig) -> Self {
+        Self {
+            log_level: config.log_level.clone(),
+            heartbeat_interval: config.heartbeat_interval,
+            max_fps: config.encoding.adaptive.max_fps,
+        }
+    }
+
+    /// Overwrite these values in `config`
+    pub fn apply(&self, config: &mut ClientConfig) {
+        config.log_level = self.log_level.clone();
+        config.heartbeat_interval = self.heartbeat_interval;
+        let adaptive = &mut config.encoding.adaptive;
+        adaptive.max_fps = self.max_fps;
+        adaptive.min_fps = adaptive.min_fps.min(self.max_fps);
+    }
+}
+
+/// Follow changes to the config file at `path`, which `current` was
+/// loaded from; the environment's values are laid over each new version,
+/// as they were over `current`
+pub fn watch(path: PathBuf, current: ClientConfig) -> watch::Receiver<LiveSettings> {
+    let (tx, rx) = watch::channel(LiveSettings::of(&current));
+    tokio::spawn(follow(path, current, tx));
+    rx
+}
+
+async fn follow(path: PathBuf, mut current: ClientConfig, tx: watch::Sender<LiveSettings>) {
+    let mut last = std::fs::read_to_string(&path).ok();
+    loop {
+        tokio::time::sleep(RELOAD_POLL).await;
+        if tx.is_closed() {
+            break;
+        }
+        // Comparing content rather than modification times catches editors
+        // that replace the file, and writes within the same second
+        let content = std::fs::read_to_string(&path).ok();
+        if content == last {
+            continue;
+        }
+        last = content;
+        let Some(content) = &last else {
+            warn!("Config {} is gone; keeping the current settings", path.display());
+            continue;
+        };
+
+        let mut next = match ClientConfig::parse(content, &path) {
+            Ok(next) => next,
+            Err(e) => {
+                warn!("Ignoring changed config: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = next.apply_env().and_then(|()| next.validate()) {
+            warn!("Ignoring changed config {}: {}", path.display(), e);
+            continue;
+        }
+
+        let live = LiveSettings::of(&next);
+        log_changes(&current, &next);
+        tx.send_if_modified(|settings| {
+            let changed = *settings != live;
+            *settings = live;
+            changed
+        });
+        current = next;
+    }
+}
+
+/// Log each value that changed, and whether it applies now or on restart
+fn log_changes(current: &ClientConfig, next: &ClientConfig) {
+    let (was, now) = (LiveSettings::of(current), LiveSettings::of(next));
+    if was.log_level != now.log_level {
+        info!("Config reloaded: log_level {:?} -> {:?}", was.log_level, now.log_level);
+    }
+    if was.heartbeat_interval != now.heartbeat_interval {
+        info!("Config reloaded: heartbeat_interval {}s -> {}s", was.heartbeat_interval, now.heartbeat_interval);
+    }
+    if was.max_fps != now.max_fps {
+        info!("Config reloaded: encoding.adaptive.max_fps {} -> {}", was.max_fps, now.max_fps);
+    }
+
+    // Everything else is compared key by key with the live values taken out
+    let mut before = current.clone();
+    let mut after = next.clone();
+    now.apply(&mut before);
+    now.apply(&mut after);
+    let (Ok(serde_json::Value::Object(before)), Ok(serde_json::Value::Object(after))) =
+        (serde_json::to_value(&before), serde_json::to_value(&after))
+    else {
+        return;
+    };
+    for (key, value) in &after {
+        if before.get(key) != Some(value) {
+            warn!("{} changed in the config; restart the agent to apply it", key);
+        }
+    }
+    for key in before.keys().filter(|key| !after.contains_key(*key)) {
+        warn!("{} was removed from the config; restart the agent to apply it", key);
+    }
+}
+
+/// Swap the log filter whenever a reload changes `log_level`, unless
+/// `--log-level` or `GHOSTLINK_LOG` picked the level
+pub fn follow_log_level(mut live: watch::Receiver<LiveSettings>, flag: Option<String>) {
+    tokio::spawn(async move {
+        let mut level = live.borrow_and_update().log_level.clone();
+        while live.changed().await.is_ok() {
+            let next = live.borrow_and_update().log_level.clone();
+            if next != level {
+                logging::set_filter(logging::level_filter(flag.as_deref(), &next));
+                level = next;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::heartbeat::HeartbeatSchedule;
+    use crate::policy::ServerPolicy;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::Instant;
+
+    fn write_config(path: &std::path::Path, heartbeat_interval: u64) {
+        let content = format!("server_url = \"wss://relay.example.com\"\nheartbeat_interval = {}\n", heartbeat_interval);
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reload_changes_the_running_heartbeat_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.toml");
+        write_config(&path, 30);
+        let config = ClientConfig::read(&path).unwrap();
+
+        let live = watch(path.clone(), config);
+        let (_policy_tx, policy_rx) = watch::channel(ServerPolicy::default());
+        let beats = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&beats);
+        let started = Instant::now();
+        tokio::spawn(async move {
+            let mut schedule = HeartbeatSchedule::new(policy_rx, live);
+            loop {
+                schedule.tick().await;
+                seen.lock().unwrap().push(started.elapsed().as_secs());
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(*beats.lock().unwrap(), [0, 30]);
+
+        // Picked up at the next poll, at 32s; the timer restarts from there
+        write_config(&path, 5);
+        tokio::time::sleep(Duration::from_secs(12)).await;
+        assert_eq!(*beats.lock().unwrap(), [0, 30, 37, 42]);
+
+        // A broken file leaves the interval alone
+        std::fs::write(&path, "heartbeat_interval = 0\n").unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(*beats.lock().unwrap(), [0, 30, 37, 42, 47, 52]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unchanged_values_are_not_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.toml");
+        write_config(&path, 30);
+        let mut live = watch(path.clone(), ClientConfig::read(&path).unwrap());
+
+        // Same values, different layout
+        std::fs::write(&path, "heartbeat_interval = 30\nserver_url = \"wss://relay.example.com\"\n").unwrap();
+        tokio::time::sleep(RELOAD_POLL * 2).await;
+        assert!(!live.has_changed().unwrap());
+
+        write_config(&path, 10);
+        tokio::time::sleep(RELOAD_POLL * 2).await;
+        assert!(live.has_changed().unwrap());
+        assert_eq!(live.borrow_and_update().heartbeat_interval, 10);
+    }
+}
@@ -0,0 +1,131 @@
+//! Checks on config values
+//!
+//! Each check runs twice: as a `deserialize_with` adapter, so a bad value
+//! in the config file fails the parse at its own line and column, and in
+//! [`ClientConfig::validate`](super::ClientConfig::validate) on the final
+//! config, since flags and environment variables set values too.
+
+use serde::de::{Deserialize, Deserializer, Error};
+
+/// Longest reconnect or heartbeat interval, in seconds
+pub const MAX_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// Highest frame rate a cap may be set to
+pub const MAX_FPS: u32 = 240;
+
+pub fn check_server_url(url: &str) -> std::result::Result<(), String> {
+    let parsed = url::Url::parse(url).map_err(|e| match unbracketed_ipv6(url) {
+        Some(bracketed) => format!("{:?} is not a URL: IPv6 addresses go in brackets, as in {:?}", url, bracketed),
+        None => format!("{:?} is not a URL: {}", url, e),
+    })?;
+    match parsed.scheme() {
+        "ws" | "wss" if parsed.host_str().is_some() => Ok(()),
+        _ => Err(format!("{:?} must be a ws:// or wss:// URL with a host", url)),
+    }
+}
+
+pub fn check_proxy_url(url: &str) -> std::result::Result<(), String> {
+    crate::connection::proxy::Proxy::parse(url, crate::connection::proxy::ProxySource::Config).map(drop)
+}
+
+/// `url` with its host in brackets, when the host is a bare IPv6 address
+fn unbracketed_ipv6(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let end = rest.find('/').unwrap_or(rest.len());
+    let (host, path) = rest.split_at(end);
+    if host.starts_with('[') || host.parse::<std::net::Ipv6Addr>().is_err() {
+        return None;
+    }
+    Some(format!("{}://[{}]{}", scheme, host, path))
+}
+
+pub fn check_interval(secs: &u64) -> std::result::Result<(), String> {
+    if (1..=MAX_INTERVAL_SECS).contains(secs) {
+        Ok(())
+    } else {
+        Err(format!("{} must be between 1 and {} seconds", secs, MAX_INTERVAL_SECS))
+    }
+}
+
+pub fn check_session_limit(limit: &u32) -> std::result::Result<(), String> {
+    if *limit == 0 {
+        return Err("0 would refuse every session; it must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+pub fn check_log_level(directives: &str) -> std::result::Result<(), String> {
+    tracing_subscriber::EnvFilter::try_new(directives)
+        .map(drop)
+        .map_err(|e| format!("{:?} is not a log level or filter: {}", directives, e))
+}
+
+pub fn check_fps(fps: &u32) -> std::result::Result<(), String> {
+    if (1..=MAX_FPS).contains(fps) {
+        Ok(())
+    } else {
+        Err(format!("{} must be between 1 and {} frames per second", fps, MAX_FPS))
+    }
+}
+
+pub fn check_cpu_budget(percent: &u32) -> std::result::Result<(), String> {
+    let range = crate::capture::budget::BUDGET_RANGE;
+    if range.contains(percent) {
+        Ok(())
+    } else {
+        Err(format!("{} must be between {} and {} percent of one core", percent, range.start(), range.end()))
+    }
+}
+
+pub fn check_memory_limit(mb: &u64) -> std::result::Result<(), String> {
+    let range = crate::resources::MEMORY_LIMIT_RANGE;
+    if range.contains(mb) {
+        Ok(())
+    } else {
+        Err(format!("{} must be between {} and {} MB", mb, range.start(), range.end()))
+    }
+}
+
+/// Deserialize a value and refuse it unless `check` passes; the checks
+/// word their refusal to follow the field's name
+fn checked<'de, D, T>(deserializer: D, check: impl Fn(&T) -> std::result::Result<(), String>) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let value = T::deserialize(deserializer)?;
+    check(&value).map_err(D::Error::custom)?;
+    Ok(value)
+}
+
+pub fn server_url<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<String, D::Error> {
+    checked(deserializer, |url: &String| check_server_url(url))
+}
+
+pub fn proxy_url<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<String>, D::Error> {
+    checked(deserializer, |url: &Option<String>| url.as_deref().map_or(Ok(()), check_proxy_url))
+}
+
+pub fn interval<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u64, D::Error> {
+    checked(deserializer, check_interval)
+}
+
+pub fn session_limit<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u32, D::Error> {
+    checked(deserializer, check_session_limit)
+}
+
+pub fn log_level<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<String, D::Error> {
+    checked(deserializer, |directives: &String| check_log_level(directives))
+}
+
+pub fn fps<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u32, D::Error> {
+    checked(deserializer, check_fps)
+}
+
+pub fn cpu_budget<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u32>, D::Error> {
+    checked(deserializer, |percent: &Option<u32>| percent.as_ref().map_or(Ok(()), check_cpu_budget))
+}
+
+pub fn memory_limit<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u64>, D::Error> {
+    checked(deserializer, |mb: &Option<u64>| mb.as_ref().map_or(Ok(()), check_memory_limit))
+}
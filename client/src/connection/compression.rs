@@ -0,0 +1,283 @@
+//! Negotiated compression of relay traffic
+//!
+//! Heartbeats with system info, session events and directory listings are
+//! JSON that compresses well. The agent offers the codecs it speaks in
+//! `AgentRegister` (`compression`) and the relay picks one in
+//! `ProtocolAccepted`; a peer that never hears the other's offer sends
+//! everything as before, so old agents and relays keep working. Once both
+//! sides agree, JSON messages of at least `threshold_bytes` travel as
+//! [`MessageKind::ControlText`] envelopes with a zstd payload, when that
+//! saves enough to be worth it.
+//!
+//! Encoded frames barely compress, but on a link slower than the encoder's
+//! target a few percent still help. Sessions that ask for it compress frame
+//! payloads with zstd level 1 while the measured throughput is below the
+//! target bitrate and the capture loop has CPU to spare; see
+//! [`should_compress_frames`].
+//!
+//! A compressed envelope carries [`flags::COMPRESSED`]. This file is kept
+//! identical in the client and the server.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+use super::protocol::{flags, DecodeError, Envelope, MessageKind, MAX_PAYLOAD_LEN};
+
+/// zstd, the only codec so far
+pub const ZSTD: &str = "zstd";
+
+/// Codecs this build speaks, offered during registration
+pub const SUPPORTED: &[&str] = &[ZSTD];
+
+/// Session setting, and `SessionRequest` field, of a session whose
+/// technician asked for compressed frames
+pub const COMPRESS_FRAMES_SETTING: &str = "compress_frames";
+
+/// Level frames are compressed at; anything slower costs more than it saves
+pub const FRAME_LEVEL: i32 = 1;
+
+/// Share of a frame interval the capture loop must leave idle before frames
+/// are compressed as well
+pub const MIN_CPU_HEADROOM: f64 = 0.3;
+
+/// Codec both sides speak, if any
+pub fn negotiate(offered: &[String]) -> Option<&'static str> {
+    SUPPORTED.iter().copied().find(|codec| offered.iter().any(|offer| offer == codec))
+}
+
+/// When JSON messages are compressed and how hard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionPolicy {
+    /// Smallest message worth compressing, in bytes
+    pub threshold_bytes: usize,
+    /// zstd level of JSON messages, 1 (fastest) to 19
+    pub level: i32,
+    /// Compressed size, as a share of the original, above which the
+    /// message goes out uncompressed
+    pub max_ratio: f64,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            threshold_bytes: 1024,
+            level: 3,
+            max_ratio: 0.9,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    /// `json` as a compressed envelope, if it is large enough and
+    /// compresses well enough
+    pub fn compress_text(&self, json: &str) -> Option<Vec<u8>> {
+        if json.len() < self.threshold_bytes {
+            return None;
+        }
+        let compressed = compress(json.as_bytes(), self.level, self.max_ratio)?;
+        let envelope = Envelope::new(MessageKind::ControlText, Uuid::nil(), &compressed)
+            .with_flags(flags::COMPRESSED)
+            .encode();
+        Some(envelope)
+    }
+}
+
+/// `data` compressed at `level`, unless that leaves more than `max_ratio`
+/// of it
+pub fn compress(data: &[u8], level: i32, max_ratio: f64) -> Option<Vec<u8>> {
+    if data.is_empty() {
+        return None;
+    }
+    let compressed = zstd::bulk::compress(data, level).ok()?;
+    (compressed.len() as f64 <= data.len() as f64 * max_ratio).then_some(compressed)
+}
+
+/// Payload of `envelope`, inflated if it was sent compressed
+pub fn payload<'a>(envelope: &Envelope<'a>) -> Result<Cow<'a, [u8]>, DecodeError> {
+    if !envelope.has_flag(flags::COMPRESSED) {
+        return Ok(Cow::Borrowed(envelope.payload));
+    }
+    let decoder = zstd::stream::read::Decoder::new(envelope.payload).map_err(|_| DecodeError::Corrupt("compressed payload"))?;
+    // A small message must not inflate into an unbounded one
+    let mut inflated = Vec::new();
+    decoder
+        .take(MAX_PAYLOAD_LEN as u64 + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|_| DecodeError::Corrupt("compressed payload"))?;
+    if inflated.len() > MAX_PAYLOAD_LEN {
+        return Err(DecodeError::TooLarge(inflated.len()));
+    }
+    Ok(Cow::Owned(inflated))
+}
+
+/// Whether a session that asked for frame compression should use it now:
+/// frames go out slower than the encoder's target and the capture loop,
+/// taking `frame_time_ms` of each `1000 / fps` ms interval, has CPU to spare
+pub fn should_compress_frames(throughput_kbps: u32, target_kbps: u32, frame_time_ms: f64, fps: u32) -> bool {
+    if target_kbps == 0 || throughput_kbps >= target_kbps || fps == 0 {
+        return false;
+    }
+    let interval_ms = 1000.0 / fps as f64;
+    let headroom = 1.0 - frame_time_ms / interval_ms;
+    headroom >= MIN_CPU_HEADROOM
+}
+
+/// Bytes before and after compression, of the messages it was tried on
+#[derive(Debug, Default)]
+pub struct CompressionCounters {
+    original: AtomicU64,
+    sent: AtomicU64,
+    compressed: AtomicU64,
+}
+
+impl CompressionCounters {
+    /// A message of `original` bytes that went out as `sent` bytes
+    pub fn record(&self, original: usize, sent: usize) {
+        self.original.fetch_add(original as u64, Ordering::Relaxed);
+        self.sent.fetch_add(sent as u64, Ordering::Relaxed);
+        if sent < original {
+            self.compressed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> ChannelCompression {
+        let original = self.original.load(Ordering::Relaxed);
+        let sent = self.sent.load(Ordering::Relaxed);
+        ChannelCompression {
+            original_bytes: original,
+            sent_bytes: sent,
+            compressed_messages: self.compressed.load(Ordering::Relaxed),
+            ratio: if original == 0 { 1.0 } else { sent as f64 / original as f64 },
+        }
+    }
+}
+
+/// Compression of one kind of traffic so far
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelCompression {
+    pub original_bytes: u64,
+    pub sent_bytes: u64,
+    pub compressed_messages: u64,
+    /// Sent bytes per original byte; 1.0 when nothing was saved
+    pub ratio: f64,
+}
+
+impl Default for ChannelCompression {
+    fn default() -> Self {
+        Self { original_bytes: 0, sent_bytes: 0, compressed_messages: 0, ratio: 1.0 }
+    }
+}
+
+/// What compression the agent's link uses, for the quality report
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionReport {
+    /// Codec agreed with the relay, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// JSON messages the agent sent
+    pub text: ChannelCompression,
+    /// Frames of this session the agent sent
+    pub frames: ChannelCompression,
+    /// Frames are being compressed right now
+    pub frames_active: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(entries: usize) -> String {
+        let entries: Vec<_> = (0..entries)
+            .map(|i| serde_json::json!({ "name": format!("report-{}.pdf", i), "size": 1024 * i, "is_dir": false }))
+            .collect();
+        serde_json::json!({ "type": "FsListResult", "entries": entries }).to_string()
+    }
+
+    #[test]
+    fn test_negotiation_falls_back_without_a_common_codec() {
+        assert_eq!(negotiate(&["zstd".to_string()]), Some(ZSTD));
+        assert_eq!(negotiate(&["brotli".to_string(), "zstd".to_string()]), Some(ZSTD));
+        // Peers from before compression offer nothing
+        assert_eq!(negotiate(&[]), None);
+        assert_eq!(negotiate(&["brotli".to_string()]), None);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let policy = CompressionPolicy::default();
+        let json = listing(200);
+        let encoded = policy.compress_text(&json).expect("a listing compresses");
+        assert!(encoded.len() < json.len() / 2, "{} of {}", encoded.len(), json.len());
+
+        let envelope = Envelope::decode(&encoded).unwrap();
+        assert_eq!(envelope.kind, MessageKind::ControlText);
+        assert!(envelope.has_flag(flags::COMPRESSED));
+        assert_eq!(payload(&envelope).unwrap().as_ref(), json.as_bytes());
+    }
+
+    #[test]
+    fn test_threshold_and_ratio() {
+        let policy = CompressionPolicy { threshold_bytes: 2048, ..Default::default() };
+        assert!(policy.compress_text(&listing(2)).is_none(), "below the threshold");
+        assert!(policy.compress_text(&listing(200)).is_some());
+
+        // Noise doesn't compress, and goes out as it is
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert!(compress(&noise, policy.level, policy.max_ratio).is_none());
+        let strict = CompressionPolicy { max_ratio: 0.01, ..Default::default() };
+        assert!(strict.compress_text(&listing(200)).is_none());
+    }
+
+    #[test]
+    fn test_uncompressed_and_corrupt_payloads() {
+        let session_id = Uuid::new_v4();
+        let plain = Envelope::new(MessageKind::ScreenFrame, session_id, b"frame");
+        assert!(matches!(payload(&plain).unwrap(), Cow::Borrowed(b"frame")));
+
+        let garbage = Envelope::new(MessageKind::ScreenFrame, session_id, b"not zstd").with_flags(flags::COMPRESSED);
+        assert_eq!(payload(&garbage).unwrap_err(), DecodeError::Corrupt("compressed payload"));
+
+        let frame = vec![7u8; 64 * 1024];
+        let compressed = compress(&frame, FRAME_LEVEL, 0.95).unwrap();
+        let envelope = Envelope::new(MessageKind::ScreenFrame, session_id, &compressed).with_flags(flags::COMPRESSED | flags::KEYFRAME);
+        let encoded = envelope.encode();
+        let decoded = Envelope::decode(&encoded).unwrap();
+        assert_eq!(payload(&decoded).unwrap().as_ref(), frame.as_slice());
+        assert!(decoded.has_flag(flags::KEYFRAME));
+    }
+
+    #[test]
+    fn test_frame_gate() {
+        // 30 fps leaves 33ms per frame
+        assert!(should_compress_frames(800, 2000, 10.0, 30));
+        assert!(!should_compress_frames(2000, 2000, 10.0, 30), "keeping up with the target");
+        assert!(!should_compress_frames(800, 2000, 28.0, 30), "little CPU headroom");
+        assert!(!should_compress_frames(800, 0, 10.0, 30));
+        assert!(!should_compress_frames(800, 2000, 10.0, 0));
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = CompressionCounters::default();
+        assert_eq!(counters.snapshot().ratio, 1.0);
+        counters.record(1000, 250);
+        counters.record(1000, 1000);
+        let snapshot = counters.snapshot();
+        assert_eq!((snapshot.original_bytes, snapshot.sent_bytes, snapshot.compressed_messages), (2000, 1250, 1));
+        assert_eq!(snapshot.ratio, 0.625);
+    }
+}
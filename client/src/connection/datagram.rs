@@ -0,0 +1,629 @@
+//! Datagram framing for the UDP video lane
+//!
+//! Video frames too large for one datagram are cut into shards, and each
+//! group of shards is followed by an XOR parity shard, so the receiver can
+//! rebuild one lost shard per group without a round trip. The receiver holds
+//! frames in a short jitter buffer to put them back in order; a frame that
+//! still cannot be rebuilt when the buffer's window passes is given up, and
+//! the stream waits for a keyframe to resynchronise.
+//!
+//! Every datagram carries the association token the relay handed out over
+//! the WebSocket, which is what ties it to an agent.
+//!
+//! This file is kept identical in the client and the server.
+//!
+//! Layout, integers little-endian:
+//!
+//! ```text
+//! packet: magic "GLUD" (4) | kind (1) | token (16) | body
+//! shard:  frame seq (4) | frame length (4) | index (2) | data shards (2) | group size (1) | flags (1) | payload
+//! ```
+//!
+//! A parity shard's index is the number of the group it covers.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// First bytes of every datagram
+pub const MAGIC: &[u8; 4] = b"GLUD";
+
+/// Bytes of an association token
+pub const TOKEN_LEN: usize = 16;
+
+/// Size of the packet header ahead of the body
+pub const PACKET_HEADER_LEN: usize = MAGIC.len() + 1 + TOKEN_LEN;
+
+/// Size of the shard header ahead of its payload
+pub const SHARD_HEADER_LEN: usize = 14;
+
+/// Frame bytes per shard, keeping datagrams below common path MTUs
+pub const SHARD_LEN: usize = 1150;
+
+/// Data shards protected by each parity shard
+pub const DEFAULT_GROUP_SIZE: u8 = 8;
+
+/// Largest frame the lane carries; bigger ones stay on the WebSocket
+pub const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// How long the receiver waits for a late or missing frame
+pub const JITTER_WINDOW: Duration = Duration::from_millis(60);
+
+/// Incomplete frames kept at most; the oldest is given up beyond that
+const MAX_PENDING_FRAMES: usize = 64;
+
+/// Shard flag bits
+pub mod flags {
+    /// Shard of a frame that decodes without earlier frames
+    pub const KEYFRAME: u8 = 1 << 0;
+    /// XOR of a group of data shards
+    pub const PARITY: u8 = 1 << 1;
+}
+
+/// Secret naming one association, issued over the WebSocket
+pub type LaneToken = [u8; TOKEN_LEN];
+
+/// What a datagram carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketKind {
+    /// Agent asking the relay to bind the association to its address
+    Hello = 1,
+    /// Relay confirming the association
+    HelloAck = 2,
+    /// One shard of a video frame
+    Shard = 3,
+    /// Liveness in either direction, echoed by the relay
+    Keepalive = 4,
+}
+
+impl TryFrom<u8> for PacketKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PacketKind::Hello),
+            2 => Ok(PacketKind::HelloAck),
+            3 => Ok(PacketKind::Shard),
+            4 => Ok(PacketKind::Keepalive),
+            other => Err(other),
+        }
+    }
+}
+
+/// Serialize one datagram
+pub fn encode_packet(kind: PacketKind, token: &LaneToken, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(PACKET_HEADER_LEN + body.len());
+    out.extend_from_slice(MAGIC);
+    out.push(kind as u8);
+    out.extend_from_slice(token);
+    out.extend_from_slice(body);
+    out
+}
+
+/// Parse a datagram into its kind, token and body; anything else is noise
+pub fn decode_packet(data: &[u8]) -> Option<(PacketKind, LaneToken, &[u8])> {
+    if data.len() < PACKET_HEADER_LEN || !data.starts_with(MAGIC) {
+        return None;
+    }
+    let kind = PacketKind::try_from(data[MAGIC.len()]).ok()?;
+    let token = data[MAGIC.len() + 1..PACKET_HEADER_LEN].try_into().ok()?;
+    Some((kind, token, &data[PACKET_HEADER_LEN..]))
+}
+
+/// One piece of a video frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shard {
+    pub frame_seq: u32,
+    pub frame_len: u32,
+    pub index: u16,
+    pub data_shards: u16,
+    pub group_size: u8,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Shard {
+    pub fn is_parity(&self) -> bool {
+        self.flags & flags::PARITY != 0
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & flags::KEYFRAME != 0
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SHARD_HEADER_LEN + self.payload.len());
+        out.extend_from_slice(&self.frame_seq.to_le_bytes());
+        out.extend_from_slice(&self.frame_len.to_le_bytes());
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.extend_from_slice(&self.data_shards.to_le_bytes());
+        out.push(self.group_size);
+        out.push(self.flags);
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    /// Parse a shard body, rejecting one whose fields contradict each other
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        if body.len() < SHARD_HEADER_LEN {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([body[at], body[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes([body[at], body[at + 1], body[at + 2], body[at + 3]]);
+        let shard = Shard {
+            frame_seq: u32_at(0),
+            frame_len: u32_at(4),
+            index: u16_at(8),
+            data_shards: u16_at(10),
+            group_size: body[12],
+            flags: body[13],
+            payload: body[SHARD_HEADER_LEN..].to_vec(),
+        };
+
+        let frame_len = shard.frame_len as usize;
+        if frame_len == 0 || frame_len > MAX_FRAME_LEN || shard.group_size == 0 {
+            return None;
+        }
+        if shard.data_shards as usize != frame_len.div_ceil(SHARD_LEN) {
+            return None;
+        }
+        let valid = if shard.is_parity() {
+            shard.index < shard.groups() && shard.payload.len() <= SHARD_LEN
+        } else {
+            shard.index < shard.data_shards && shard.payload.len() == shard_len(frame_len, shard.index)
+        };
+        valid.then_some(shard)
+    }
+
+    fn groups(&self) -> u16 {
+        self.data_shards.div_ceil(u16::from(self.group_size))
+    }
+}
+
+/// Bytes of data shard `index` of a frame of `frame_len` bytes
+fn shard_len(frame_len: usize, index: u16) -> usize {
+    (frame_len - index as usize * SHARD_LEN).min(SHARD_LEN)
+}
+
+/// Cuts frames into shards and adds parity
+#[derive(Debug)]
+pub struct FecEncoder {
+    group_size: u8,
+    next_frame: u32,
+}
+
+impl Default for FecEncoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_GROUP_SIZE)
+    }
+}
+
+impl FecEncoder {
+    pub fn new(group_size: u8) -> Self {
+        Self { group_size: group_size.max(1), next_frame: 0 }
+    }
+
+    /// Shards of the next frame, each group's parity right after its data
+    pub fn encode(&mut self, frame: &[u8], keyframe: bool) -> Vec<Shard> {
+        assert!(!frame.is_empty() && frame.len() <= MAX_FRAME_LEN, "frame of {} bytes cannot be sharded", frame.len());
+        let frame_seq = self.next_frame;
+        self.next_frame = self.next_frame.wrapping_add(1);
+
+        let data_shards = frame.len().div_ceil(SHARD_LEN) as u16;
+        let frame_flags = if keyframe { flags::KEYFRAME } else { 0 };
+        let shard = |index: u16, flags: u8, payload: Vec<u8>| Shard {
+            frame_seq,
+            frame_len: frame.len() as u32,
+            index,
+            data_shards,
+            group_size: self.group_size,
+            flags,
+            payload,
+        };
+
+        let mut shards = Vec::new();
+        for (group, chunks) in frame.chunks(SHARD_LEN * self.group_size as usize).enumerate() {
+            let mut parity = vec![0u8; chunks.len().min(SHARD_LEN)];
+            for (offset, chunk) in chunks.chunks(SHARD_LEN).enumerate() {
+                xor_into(&mut parity, chunk);
+                let index = group * self.group_size as usize + offset;
+                shards.push(shard(index as u16, frame_flags, chunk.to_vec()));
+            }
+            shards.push(shard(group as u16, frame_flags | flags::PARITY, parity));
+        }
+        shards
+    }
+}
+
+fn xor_into(target: &mut [u8], data: &[u8]) {
+    for (byte, other) in target.iter_mut().zip(data) {
+        *byte ^= other;
+    }
+}
+
+/// A frame put back together by the receiver
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedFrame {
+    pub seq: u32,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// Counters of what the jitter buffer saw
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReceiverStats {
+    pub frames: u64,
+    /// Shards rebuilt from parity
+    pub recovered_shards: u64,
+    /// Frames given up as unrecoverable
+    pub lost_frames: u64,
+}
+
+#[derive(Debug)]
+struct PendingFrame {
+    first_seen: Instant,
+    frame_len: usize,
+    group_size: u8,
+    keyframe: bool,
+    data: Vec<Option<Vec<u8>>>,
+    parity: Vec<Option<Vec<u8>>>,
+}
+
+impl PendingFrame {
+    fn new(shard: &Shard, now: Instant) -> Self {
+        Self {
+            first_seen: now,
+            frame_len: shard.frame_len as usize,
+            group_size: shard.group_size,
+            keyframe: false,
+            data: vec![None; shard.data_shards as usize],
+            parity: vec![None; shard.groups() as usize],
+        }
+    }
+
+    fn matches(&self, shard: &Shard) -> bool {
+        self.frame_len == shard.frame_len as usize && self.group_size == shard.group_size
+    }
+
+    /// Fill each group missing a single shard from its parity; `None`
+    /// while some group misses more than its parity covers
+    fn recover(&mut self) -> Option<u64> {
+        let group_size = self.group_size as usize;
+        let mut rebuild = Vec::new();
+        for (group, parity) in self.parity.iter().enumerate() {
+            let start = group * group_size;
+            let end = (start + group_size).min(self.data.len());
+            let mut missing = (start..end).filter(|&index| self.data[index].is_none());
+            match (missing.next(), missing.next(), parity) {
+                (None, _, _) => {}
+                (Some(index), None, Some(parity)) if parity.len() >= shard_len(self.frame_len, index as u16) => {
+                    rebuild.push((group, index));
+                }
+                _ => return None,
+            }
+        }
+
+        for &(group, index) in &rebuild {
+            let start = group * group_size;
+            let end = (start + group_size).min(self.data.len());
+            let mut rebuilt = self.parity[group].clone().expect("parity checked above");
+            for shard in self.data[start..end].iter().flatten() {
+                xor_into(&mut rebuilt, shard);
+            }
+            rebuilt.truncate(shard_len(self.frame_len, index as u16));
+            self.data[index] = Some(rebuilt);
+        }
+        Some(rebuild.len() as u64)
+    }
+
+    fn assemble(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.frame_len);
+        for shard in self.data.into_iter().flatten() {
+            data.extend_from_slice(&shard);
+        }
+        data
+    }
+}
+
+/// Puts frames back together and in order
+#[derive(Debug)]
+pub struct JitterBuffer {
+    window: Duration,
+    /// Sequence number of the next frame to hand out
+    next: Option<u32>,
+    pending: BTreeMap<u32, PendingFrame>,
+    /// A frame was given up; deltas are useless until the next keyframe
+    awaiting_keyframe: bool,
+    keyframe_wanted: bool,
+    stats: ReceiverStats,
+}
+
+impl Default for JitterBuffer {
+    fn default() -> Self {
+        Self::new(JITTER_WINDOW)
+    }
+}
+
+impl JitterBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            next: None,
+            pending: BTreeMap::new(),
+            awaiting_keyframe: false,
+            keyframe_wanted: false,
+            stats: ReceiverStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReceiverStats {
+        self.stats
+    }
+
+    /// Whether a frame was lost since the last call, so the sender owes a keyframe
+    pub fn take_keyframe_request(&mut self) -> bool {
+        std::mem::take(&mut self.keyframe_wanted)
+    }
+
+    pub fn push(&mut self, shard: Shard, now: Instant) {
+        let next = *self.next.get_or_insert(shard.frame_seq);
+        if shard.frame_seq < next {
+            // Already delivered or given up
+            return;
+        }
+
+        let pending = self.pending.entry(shard.frame_seq).or_insert_with(|| PendingFrame::new(&shard, now));
+        if !pending.matches(&shard) {
+            return;
+        }
+        pending.keyframe |= shard.is_keyframe();
+        let slot = if shard.is_parity() { &mut pending.parity } else { &mut pending.data };
+        slot[shard.index as usize] = Some(shard.payload);
+
+        while self.pending.len() > MAX_PENDING_FRAMES {
+            self.give_up_next();
+        }
+    }
+
+    /// Frames ready in order; a missing frame holds back later ones until
+    /// the oldest of them has waited a full window
+    pub fn poll(&mut self, now: Instant) -> Vec<ReceivedFrame> {
+        let mut ready = Vec::new();
+        while let Some(next) = self.next {
+            let recovered = self.pending.get_mut(&next).and_then(PendingFrame::recover);
+            match recovered {
+                Some(recovered) => {
+                    let frame = self.pending.remove(&next).expect("frame was pending");
+                    self.next = Some(next.wrapping_add(1));
+                    self.stats.recovered_shards += recovered;
+                    if self.awaiting_keyframe && !frame.keyframe {
+                        continue;
+                    }
+                    self.awaiting_keyframe = false;
+                    self.stats.frames += 1;
+                    ready.push(ReceivedFrame { seq: next, keyframe: frame.keyframe, data: frame.assemble() });
+                }
+                None => {
+                    let oldest = self.pending.values().map(|frame| frame.first_seen).min();
+                    match oldest {
+                        Some(oldest) if now.duration_since(oldest) >= self.window => self.give_up_next(),
+                        _ => break,
+                    }
+                }
+            }
+        }
+        ready
+    }
+
+    /// Give up the frame being waited for, or the whole gap up to the
+    /// oldest pending frame if none of it arrived
+    fn give_up_next(&mut self) {
+        let (Some(next), Some(&oldest)) = (self.next, self.pending.keys().next()) else {
+            return;
+        };
+        let skipped = if oldest == next {
+            self.pending.remove(&next);
+            1
+        } else {
+            oldest.wrapping_sub(next)
+        };
+        self.next = Some(next.wrapping_add(skipped));
+        self.stats.lost_frames += u64::from(skipped);
+        self.awaiting_keyframe = true;
+        self.keyframe_wanted = true;
+    }
+}
+
+/// State of a lane as seen from one end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaneStatus {
+    /// Nothing heard yet, still inside the probe window
+    Probing,
+    Up,
+    /// Blocked or gone quiet; traffic belongs back on the WebSocket
+    Failed,
+}
+
+/// How long each end waits on the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneTimings {
+    /// Time for the first packet to arrive before UDP counts as blocked
+    pub probe_window: Duration,
+    pub keepalive_interval: Duration,
+    /// Silence after which an established lane counts as lost
+    pub silence_timeout: Duration,
+}
+
+impl Default for LaneTimings {
+    fn default() -> Self {
+        Self {
+            probe_window: Duration::from_secs(3),
+            keepalive_interval: Duration::from_secs(1),
+            silence_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Tracks whether the other end of a lane is still heard from
+#[derive(Debug, Clone)]
+pub struct LaneMonitor {
+    timings: LaneTimings,
+    opened: Instant,
+    last_heard: Option<Instant>,
+}
+
+impl LaneMonitor {
+    pub fn new(timings: LaneTimings, now: Instant) -> Self {
+        Self { timings, opened: now, last_heard: None }
+    }
+
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = Some(now);
+    }
+
+    pub fn status(&self, now: Instant) -> LaneStatus {
+        match self.last_heard {
+            None if now.duration_since(self.opened) < self.timings.probe_window => LaneStatus::Probing,
+            Some(last) if now.duration_since(last) < self.timings.silence_timeout => LaneStatus::Up,
+            _ => LaneStatus::Failed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    /// Shards as they come off the wire
+    fn wire(shards: Vec<Shard>) -> Vec<Shard> {
+        shards.into_iter().map(|shard| Shard::decode(&shard.encode()).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let token = [7u8; TOKEN_LEN];
+        let packet = encode_packet(PacketKind::Shard, &token, b"body");
+        assert_eq!(decode_packet(&packet), Some((PacketKind::Shard, token, &b"body"[..])));
+
+        assert_eq!(decode_packet(b"GLUD"), None);
+        let mut unknown = packet.clone();
+        unknown[4] = 9;
+        assert_eq!(decode_packet(&unknown), None);
+        // Shards that contradict their own frame length are dropped
+        let mut shard = FecEncoder::default().encode(&frame(3000, 1), false).remove(0);
+        shard.data_shards = 7;
+        assert_eq!(Shard::decode(&shard.encode()), None);
+    }
+
+    #[test]
+    fn test_fec_rebuilds_one_lost_shard_per_group() {
+        let mut encoder = FecEncoder::new(4);
+        // 10 data shards in groups of 4, 4 and 2, the last one short
+        let original = frame(SHARD_LEN * 9 + 100, 3);
+        let shards = wire(encoder.encode(&original, true));
+        assert_eq!(shards.len(), 13);
+        assert_eq!(shards.iter().filter(|shard| shard.is_parity()).count(), 3);
+
+        let now = Instant::now();
+        let mut buffer = JitterBuffer::default();
+        // Lose one data shard in every group, including the short last one
+        for shard in shards {
+            if !shard.is_parity() && [1, 4, 9].contains(&shard.index) {
+                continue;
+            }
+            buffer.push(shard, now);
+        }
+        let frames = buffer.poll(now);
+        assert_eq!(frames, [ReceivedFrame { seq: 0, keyframe: true, data: original }]);
+        assert_eq!(buffer.stats().recovered_shards, 3);
+        assert!(!buffer.take_keyframe_request());
+
+        // Two losses in one group are beyond the parity
+        let original = frame(SHARD_LEN * 4, 5);
+        for shard in wire(encoder.encode(&original, false)) {
+            if shard.is_parity() || shard.index > 1 {
+                buffer.push(shard, now);
+            }
+        }
+        assert!(buffer.poll(now).is_empty());
+        assert!(buffer.poll(now + JITTER_WINDOW).is_empty());
+        assert_eq!(buffer.stats().lost_frames, 1);
+        assert!(buffer.take_keyframe_request());
+        assert!(!buffer.take_keyframe_request());
+    }
+
+    #[test]
+    fn test_jitter_buffer_reorders_frames() {
+        let mut encoder = FecEncoder::default();
+        let frames: Vec<Vec<u8>> = (0..4).map(|seed| frame(2500, seed)).collect();
+        let mut shards: Vec<Vec<Shard>> = frames.iter().map(|data| wire(encoder.encode(data, false))).collect();
+
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::default();
+        for shard in shards.remove(0) {
+            buffer.push(shard, start);
+        }
+        // Frame 2 and 3 overtake frame 1
+        for shard in shards.remove(1).into_iter().chain(shards.remove(1)) {
+            buffer.push(shard, start);
+        }
+        let delivered: Vec<u32> = buffer.poll(start).iter().map(|frame| frame.seq).collect();
+        assert_eq!(delivered, [0]);
+
+        let later = start + JITTER_WINDOW / 2;
+        for shard in shards.remove(0) {
+            buffer.push(shard, later);
+        }
+        let delivered = buffer.poll(later);
+        assert_eq!(delivered.iter().map(|frame| frame.seq).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(delivered[1].data, frames[2]);
+        assert_eq!(buffer.stats(), ReceiverStats { frames: 4, recovered_shards: 0, lost_frames: 0 });
+    }
+
+    #[test]
+    fn test_lost_frame_holds_deltas_until_keyframe() {
+        let mut encoder = FecEncoder::default();
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::default();
+        let mut push = |buffer: &mut JitterBuffer, keyframe: bool, drop: bool| {
+            let shards = wire(encoder.encode(&frame(500, 0), keyframe));
+            if !drop {
+                for shard in shards {
+                    buffer.push(shard, start);
+                }
+            }
+        };
+        push(&mut buffer, true, false);
+        // Frame 1 never arrives, not even its parity
+        push(&mut buffer, false, true);
+        push(&mut buffer, false, false);
+        push(&mut buffer, true, false);
+        push(&mut buffer, false, false);
+
+        assert_eq!(buffer.poll(start).len(), 1);
+        let delivered: Vec<(u32, bool)> = buffer.poll(start + JITTER_WINDOW).iter().map(|frame| (frame.seq, frame.keyframe)).collect();
+        // The delta after the gap is useless without frame 1
+        assert_eq!(delivered, [(3, true), (4, false)]);
+        assert!(buffer.take_keyframe_request());
+        // Stragglers of frames already given up are ignored
+        buffer.push(wire(FecEncoder::default().encode(&frame(500, 0), false)).remove(0), start);
+        assert!(buffer.poll(start + JITTER_WINDOW).is_empty());
+    }
+
+    #[test]
+    fn test_lane_monitor() {
+        let timings = LaneTimings::default();
+        let opened = Instant::now();
+        let mut monitor = LaneMonitor::new(timings, opened);
+        assert_eq!(monitor.status(opened + Duration::from_secs(1)), LaneStatus::Probing);
+        assert_eq!(monitor.status(opened + timings.probe_window), LaneStatus::Failed);
+
+        monitor.heard(opened + Duration::from_secs(1));
+        assert_eq!(monitor.status(opened + Duration::from_secs(4)), LaneStatus::Up);
+        assert_eq!(monitor.status(opened + Duration::from_secs(1) + timings.silence_timeout), LaneStatus::Failed);
+    }
+}
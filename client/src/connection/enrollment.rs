@@ -0,0 +1,265 @@
+//! Device enrollment with the relay
+//!
+//! `install --enroll-token` (or `start --enroll-token`) generates an Ed25519
+//! keypair, posts the public key to `/relay/enroll` together with the token
+//! an admin minted, and stores the private key in the client config.
+//! `enroll --code` does the same with the code of an invitation. Every
+//! relay registration afterwards carries a signature over the agent id and
+//! the current time, which the relay checks against the enrolled key.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::config::ClientConfig;
+use crate::error::{ConnectionError, Context, GhostLinkError, Result};
+
+/// Prefix of the bytes signed to register; must match the server
+pub const REGISTRATION_CONTEXT: &str = "ghostlink-agent-register-v1";
+
+/// Prefix of the bytes signed to upload a diagnostics bundle; must match the server
+pub const DIAGNOSTICS_CONTEXT: &str = "ghostlink-agent-diagnostics-v1";
+
+/// Prefix of the bytes signed to upload part of a session recording; must
+/// match the server
+pub const RECORDING_CONTEXT: &str = "ghostlink-agent-recording-v1";
+
+/// Prefix of the bytes signed to upload a crash report; must match the server
+pub const CRASH_REPORT_CONTEXT: &str = "ghostlink-agent-crash-report-v1";
+
+/// Relay close code: the registration message was malformed
+pub const CLOSE_BAD_REGISTRATION: u16 = 4400;
+/// Relay close code: the agent is not enrolled or did not sign
+pub const CLOSE_NOT_ENROLLED: u16 = 4401;
+/// Relay close code: the registration signature did not verify
+pub const CLOSE_INVALID_SIGNATURE: u16 = 4403;
+/// Relay close code: the support code is unknown, expired or used
+pub const CLOSE_INVALID_SUPPORT_CODE: u16 = 4404;
+/// Relay close code: another connection registered with this agent's ID
+pub const CLOSE_REPLACED: u16 = 4409;
+/// Relay close code: this address holds as many connections as the relay allows
+pub const CLOSE_TOO_MANY_CONNECTIONS: u16 = 4429;
+/// Relay close code: the relay is at its connection ceiling
+pub const CLOSE_SERVER_BUSY: u16 = 4503;
+
+/// Bytes signed to register
+pub fn registration_payload(agent_id: &str, timestamp: i64) -> String {
+    format!("{}:{}:{}", REGISTRATION_CONTEXT, agent_id, timestamp)
+}
+
+/// Bytes signed to upload a diagnostics bundle with this SHA-256
+pub fn diagnostics_payload(agent_id: &str, timestamp: i64, sha256_hex: &str) -> String {
+    format!("{}:{}:{}:{}", DIAGNOSTICS_CONTEXT, agent_id, timestamp, sha256_hex)
+}
+
+/// Bytes signed to upload a recording declaration or chunk with this SHA-256
+pub fn recording_payload(agent_id: &str, timestamp: i64, sha256_hex: &str) -> String {
+    format!("{}:{}:{}:{}", RECORDING_CONTEXT, agent_id, timestamp, sha256_hex)
+}
+
+/// Bytes signed to upload a crash report with this SHA-256
+pub fn crash_report_payload(agent_id: &str, timestamp: i64, sha256_hex: &str) -> String {
+    format!("{}:{}:{}:{}", CRASH_REPORT_CONTEXT, agent_id, timestamp, sha256_hex)
+}
+
+/// The agent's enrolled signing key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceCredential {
+    /// Base64-encoded PKCS#8 Ed25519 keypair
+    pub private_key: String,
+}
+
+impl DeviceCredential {
+    pub fn generate() -> Result<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| GhostLinkError::Other("Failed to generate device key".to_string()))?;
+        Ok(Self {
+            private_key: BASE64.encode(pkcs8.as_ref()),
+        })
+    }
+
+    fn keypair(&self) -> Result<Ed25519KeyPair> {
+        let pkcs8 = BASE64.decode(&self.private_key)
+            .context("Device key is not valid base64")?;
+        Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|_| GhostLinkError::Other("Device key is not a valid Ed25519 key".to_string()))
+    }
+
+    pub fn public_key_base64(&self) -> Result<String> {
+        Ok(BASE64.encode(self.keypair()?.public_key().as_ref()))
+    }
+
+    /// Base64 signature for an `AgentRegister` message sent at `timestamp`
+    pub fn sign_registration(&self, agent_id: &str, timestamp: i64) -> Result<String> {
+        let signature = self.keypair()?.sign(registration_payload(agent_id, timestamp).as_bytes());
+        Ok(BASE64.encode(signature.as_ref()))
+    }
+
+    /// Base64 signature for uploading a diagnostics bundle at `timestamp`
+    pub fn sign_diagnostics(&self, agent_id: &str, timestamp: i64, sha256_hex: &str) -> Result<String> {
+        let signature = self.keypair()?.sign(diagnostics_payload(agent_id, timestamp, sha256_hex).as_bytes());
+        Ok(BASE64.encode(signature.as_ref()))
+    }
+
+    /// Base64 signature for uploading part of a recording at `timestamp`
+    pub fn sign_recording(&self, agent_id: &str, timestamp: i64, sha256_hex: &str) -> Result<String> {
+        let signature = self.keypair()?.sign(recording_payload(agent_id, timestamp, sha256_hex).as_bytes());
+        Ok(BASE64.encode(signature.as_ref()))
+    }
+
+    /// Base64 signature for uploading a crash report at `timestamp`
+    pub fn sign_crash_report(&self, agent_id: &str, timestamp: i64, sha256_hex: &str) -> Result<String> {
+        let signature = self.keypair()?.sign(crash_report_payload(agent_id, timestamp, sha256_hex).as_bytes());
+        Ok(BASE64.encode(signature.as_ref()))
+    }
+}
+
+/// HTTP endpoint for enrollment, derived from the relay WebSocket URL
+/// (`wss://host/relay/ws` becomes `https://host/relay/enroll`)
+pub fn enroll_url(server_url: &str) -> Result<Url> {
+    let invalid = |reason: String| ConnectionError::InvalidServerUrl { url: server_url.to_string(), reason };
+    let mut url = Url::parse(server_url).map_err(|e| invalid(e.to_string()))?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        other => return Err(invalid(format!("unsupported scheme {}", other)).into()),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| invalid("cannot be turned into an HTTP URL".to_string()))?;
+
+    let path = match url.path().strip_suffix("/ws") {
+        Some(base) => format!("{}/enroll", base),
+        None => format!("{}/relay/enroll", url.path().trim_end_matches('/')),
+    };
+    url.set_path(&path);
+    url.set_query(None);
+    Ok(url)
+}
+
+/// What an admin handed out to enroll the device with
+#[derive(Debug, Clone)]
+pub enum EnrollWith {
+    /// Enrollment token, minted directly or from an invitation's QR code
+    Token(String),
+    /// Invitation code
+    Code(String),
+}
+
+#[derive(Serialize)]
+struct EnrollRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+    agent_id: &'a str,
+    hostname: &'a str,
+    platform: &'a str,
+    public_key: String,
+}
+
+/// Enroll this agent using an admin-issued token or invitation code and
+/// return the new credential.
+///
+/// The caller stores the credential in the client config.
+pub async fn enroll(config: &ClientConfig, with: &EnrollWith) -> Result<DeviceCredential> {
+    let credential = DeviceCredential::generate()?;
+    let url = enroll_url(&config.server_url)?;
+    let (token, code) = match with {
+        EnrollWith::Token(token) => (Some(token.as_str()), None),
+        EnrollWith::Code(code) => (None, Some(code.as_str())),
+    };
+    let request = EnrollRequest {
+        token,
+        code,
+        agent_id: &config.agent_id,
+        hostname: &config.hostname,
+        platform: std::env::consts::OS,
+        public_key: credential.public_key_base64()?,
+    };
+
+    let response = super::proxy::http_client()
+        .post(url.clone())
+        .json(&request)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body: serde_json::Value = response.json().await.unwrap_or_default();
+        let reason = body.get("error").and_then(|e| e.as_str()).unwrap_or("no details");
+        return Err(ConnectionError::AuthenticationFailed {
+            agent_id: config.agent_id.clone(),
+            reason: format!("enrollment rejected ({}): {}", status, reason),
+        }
+        .into());
+    }
+
+    Ok(credential)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_signature_verifies_with_public_key() {
+        let credential = DeviceCredential::generate().unwrap();
+        let signature = credential.sign_registration("agent-1", 1_700_000_000).unwrap();
+
+        let public_key = BASE64.decode(credential.public_key_base64().unwrap()).unwrap();
+        let signature = BASE64.decode(signature).unwrap();
+        let verifier = UnparsedPublicKey::new(&ED25519, public_key);
+        verifier.verify(registration_payload("agent-1", 1_700_000_000).as_bytes(), &signature).unwrap();
+        assert!(verifier.verify(registration_payload("agent-1", 1_700_000_001).as_bytes(), &signature).is_err());
+    }
+
+    #[test]
+    fn test_enroll_url_from_relay_url() {
+        assert_eq!(enroll_url("wss://relay.example.com/relay/ws").unwrap().as_str(), "https://relay.example.com/relay/enroll");
+        assert_eq!(enroll_url("ws://localhost:8080/relay/ws?x=1").unwrap().as_str(), "http://localhost:8080/relay/enroll");
+        assert_eq!(enroll_url("wss://relay.example.com").unwrap().as_str(), "https://relay.example.com/relay/enroll");
+        assert!(matches!(
+            enroll_url("ftp://relay.example.com"),
+            Err(GhostLinkError::Connection(ConnectionError::InvalidServerUrl { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_enroll_with_invitation_code() {
+        let server = MockServer::start().await;
+        let server_url = format!("{}/relay/ws", server.uri().replace("http", "ws"));
+        let config = ClientConfig::new(server_url, Some("till-01".to_string())).unwrap();
+        Mock::given(method("POST"))
+            .and(path("/relay/enroll"))
+            .and(body_partial_json(serde_json::json!({ "code": "ABCD2345", "agent_id": config.agent_id })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "status": "enrolled" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let credential = enroll(&config, &EnrollWith::Code("ABCD2345".to_string())).await.unwrap();
+        let request = &server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["public_key"], credential.public_key_base64().unwrap());
+        assert!(body.get("token").is_none());
+
+        // The server's reason reaches the person enrolling
+        Mock::given(method("POST"))
+            .and(path("/relay/enroll"))
+            .and(body_partial_json(serde_json::json!({ "code": "USED2345" })))
+            .respond_with(ResponseTemplate::new(410).set_body_json(serde_json::json!({
+                "error": "invitation code was already used by as many devices as it allows"
+            })))
+            .mount(&server)
+            .await;
+        let err = enroll(&config, &EnrollWith::Code("USED2345".to_string())).await.unwrap_err();
+        assert!(matches!(&err, GhostLinkError::Connection(ConnectionError::AuthenticationFailed { agent_id, .. }) if *agent_id == config.agent_id));
+        assert!(err.to_string().contains("410"));
+        assert!(err.to_string().contains("already used by as many devices"));
+    }
+}
@@ -1,18 +1,52 @@
-use anyhow::Result;
+//! Direct-or-relayed session transport
+//!
+//! Every session starts on the relay. The side that wants a direct path sends
+//! its STUN-discovered addresses in a `P2PHandshake`; the peer answers with a
+//! `P2PResponse` and both punch towards each other. If the direct channel comes
+//! up, frames and input move onto it; if punching times out or the channel
+//! later goes quiet, traffic stays on (or returns to) the relay without the
+//! caller noticing.
+//!
+//! Sessions the relay offers a UDP lane send their frames over it while no
+//! direct channel is up; everything else keeps to the relay's WebSocket, and
+//! so do frames again once the lane goes quiet.
+
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 use tokio::time::{Duration, timeout};
 use tracing::{debug, info, warn};
 
+use super::datagram::{self, LaneStatus};
+use super::outbound::MessagePriority;
+use super::p2p::DirectChannel;
+use super::udp_lane::UdpLane;
 use super::{RelayConnection, RelayMessage, P2PManager, P2PConnectionInfo};
+use crate::error::{ConnectionError, GhostLinkError, Result};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How often an idle direct channel is kept alive
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Messages from the direct channel waiting for the session
+const INCOMING_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConnectionType {
     Direct,         // Direct P2P (RustDesk-style)
     Relay,          // Through GhostLink server (ScreenConnect-style)
-    Hybrid,         // Both P2P + relay fallback
+    RelayUdp,       // Frames over the relay's UDP lane, the rest through the server
+}
+
+impl std::fmt::Display for ConnectionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionType::Direct => write!(f, "Direct"),
+            ConnectionType::Relay => write!(f, "Relayed"),
+            ConnectionType::RelayUdp => write!(f, "Relayed (UDP)"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +57,11 @@ pub struct ConnectionSettings {
     pub p2p_timeout: Duration,
     pub force_relay: bool,
     pub encryption_required: bool,
+    /// STUN servers used to discover the public address, as host:port with
+    /// IPv6 literals in brackets
+    pub stun_servers: Vec<String>,
+    /// Silence after which a direct channel is abandoned for the relay
+    pub idle_timeout: Duration,
 }
 
 impl Default for ConnectionSettings {
@@ -34,353 +73,414 @@ impl Default for ConnectionSettings {
             p2p_timeout: Duration::from_secs(5),
             force_relay: false,
             encryption_required: true,
+            stun_servers: vec![
+                "stun.l.google.com:19302".to_string(),
+                "stun1.l.google.com:19302".to_string(),
+            ],
+            idle_timeout: Duration::from_secs(5),
         }
     }
 }
 
-pub struct HybridConnectionManager {
+/// The relay path a session falls back to
+#[async_trait]
+pub trait RelayLink: Send + Sync {
+    async fn send_message(&self, message: RelayMessage) -> Result<()>;
+    async fn send_binary(&self, data: Vec<u8>, priority: MessagePriority) -> Result<()>;
+    async fn is_healthy(&self) -> bool;
+}
+
+/// The agent's shared relay connection
+#[async_trait]
+impl RelayLink for RwLock<Option<RelayConnection>> {
+    async fn send_message(&self, message: RelayMessage) -> Result<()> {
+        match self.read().await.as_ref() {
+            Some(relay) => relay.send_message(message).await,
+            None => Err(ConnectionError::NotConnected.into()),
+        }
+    }
+
+    async fn send_binary(&self, data: Vec<u8>, priority: MessagePriority) -> Result<()> {
+        match self.read().await.as_ref() {
+            Some(relay) => relay.send_binary(data, priority).await,
+            None => Err(ConnectionError::NotConnected.into()),
+        }
+    }
+
+    async fn is_healthy(&self) -> bool {
+        match self.read().await.as_ref() {
+            Some(relay) => relay.is_healthy().await,
+            None => false,
+        }
+    }
+}
+
+struct Inner {
     session_id: String,
-    relay_connection: Arc<Mutex<Option<RelayConnection>>>,
-    p2p_manager: Arc<Mutex<Option<P2PManager>>>,
-    connection_type: Arc<RwLock<ConnectionType>>,
+    relay: Arc<dyn RelayLink>,
     settings: ConnectionSettings,
-    relay_servers: Vec<SocketAddr>,
-    rendezvous_server: SocketAddr,
+    connection_type: RwLock<ConnectionType>,
+    direct: RwLock<Option<Arc<DirectChannel>>>,
+    udp_lane: RwLock<Option<Arc<UdpLane>>>,
+    /// Waiting `connect` call, completed by the peer's `P2PResponse`
+    pending_response: Mutex<Option<oneshot::Sender<Option<P2PConnectionInfo>>>>,
+    incoming_tx: mpsc::Sender<Vec<u8>>,
+    incoming_rx: Mutex<Option<mpsc::Receiver<Vec<u8>>>>,
+}
+
+/// Chooses between a direct channel and the relay for one session
+#[derive(Clone)]
+pub struct HybridConnectionManager {
+    inner: Arc<Inner>,
 }
 
 impl HybridConnectionManager {
-    pub async fn new(
+    pub fn new(
         session_id: String,
-        relay_servers: Vec<SocketAddr>,
-        rendezvous_server: SocketAddr,
+        relay: Arc<dyn RelayLink>,
         settings: ConnectionSettings,
-    ) -> Result<Self> {
+    ) -> Self {
         info!("Creating hybrid connection manager for session: {}", session_id);
-        
-        Ok(Self {
-            session_id,
-            relay_connection: Arc::new(Mutex::new(None)),
-            p2p_manager: Arc::new(Mutex::new(None)),
-            connection_type: Arc::new(RwLock::new(ConnectionType::Hybrid)),
-            settings,
-            relay_servers,
-            rendezvous_server,
-        })
-    }
-    
-    /// Establish connection using best available method
-    /// Combines ScreenConnect reliability with RustDesk P2P performance
-    pub async fn connect(&self, peer_id: String) -> Result<()> {
-        info!("Establishing hybrid connection to peer: {}", peer_id);
-        
-        if self.settings.force_relay {
-            return self.connect_relay_only().await;
-        }
-        
-        if self.settings.prefer_p2p {
-            // RustDesk approach: try P2P first for performance
-            match self.try_p2p_connection(&peer_id).await {
-                Ok(()) => {
-                    info!("P2P connection established successfully");
-                    let mut conn_type = self.connection_type.write().await;
-                    *conn_type = ConnectionType::Direct;
-                    return Ok(());
-                }
-                Err(e) => {
-                    warn!("P2P connection failed: {}", e);
-                    if !self.settings.allow_relay_fallback {
-                        return Err(e);
-                    }
-                }
-            }
+        let (incoming_tx, incoming_rx) = mpsc::channel(INCOMING_CAPACITY);
+
+        Self {
+            inner: Arc::new(Inner {
+                session_id,
+                relay,
+                settings,
+                connection_type: RwLock::new(ConnectionType::Relay),
+                direct: RwLock::new(None),
+                udp_lane: RwLock::new(None),
+                pending_response: Mutex::new(None),
+                incoming_tx,
+                incoming_rx: Mutex::new(Some(incoming_rx)),
+            }),
         }
-        
-        // ScreenConnect approach: reliable relay connection
-        info!("Falling back to relay connection");
-        self.connect_relay_only().await?;
-        
-        let mut conn_type = self.connection_type.write().await;
-        *conn_type = ConnectionType::Relay;
-        
-        Ok(())
     }
-    
-    async fn try_p2p_connection(&self, peer_id: &str) -> Result<()> {
-        info!("Attempting P2P connection using RustDesk-style approach");
-        
-        // Initialize P2P manager
-        let p2p_manager = P2PManager::new(
-            self.session_id.clone(),
-            self.relay_servers.clone(),
-            self.rendezvous_server,
-        ).await?;
-        
-        // Store P2P manager
-        {
-            let mut p2p_guard = self.p2p_manager.lock().await;
-            *p2p_guard = Some(p2p_manager);
-        }
-        
-        // Exchange P2P info through relay server first (like RustDesk rendezvous)
-        self.exchange_p2p_info(peer_id).await?;
-        
-        // Wait for P2P handshake response
-        timeout(
-            self.settings.p2p_timeout,
-            self.wait_for_p2p_handshake()
-        ).await??;
-        
-        info!("P2P handshake completed successfully");
-        Ok(())
+
+    pub fn session_id(&self) -> &str {
+        &self.inner.session_id
     }
-    
-    async fn exchange_p2p_info(&self, peer_id: &str) -> Result<()> {
-        // Get our P2P connection info
-        let connection_info = {
-            let p2p_guard = self.p2p_manager.lock().await;
-            if let Some(ref p2p) = *p2p_guard {
-                p2p.get_local_info().clone()
-            } else {
-                return Err(anyhow::anyhow!("P2P manager not initialized"));
-            }
-        };
-        
-        // Send P2P handshake through relay (rendezvous)
-        let handshake_msg = RelayMessage::P2PHandshake {
-            session_id: self.session_id.clone(),
-            connection_info,
-        };
-        
-        // We need a relay connection for the handshake
-        self.ensure_relay_connection().await?;
-        
-        let relay_guard = self.relay_connection.lock().await;
-        if let Some(ref relay) = *relay_guard {
-            relay.send_message(handshake_msg).await?;
-            info!("P2P handshake sent to peer: {}", peer_id);
-        } else {
-            return Err(anyhow::anyhow!("Relay connection required for P2P handshake"));
-        }
-        
-        Ok(())
+
+    /// Which path traffic currently takes
+    pub async fn connection_type(&self) -> ConnectionType {
+        *self.inner.connection_type.read().await
     }
-    
-    async fn wait_for_p2p_handshake(&self) -> Result<()> {
-        // TODO: Implement actual handshake response waiting
-        // This would listen for P2PResponse messages from the relay
-        info!("Waiting for P2P handshake response...");
-        
-        // Simulate handshake completion for now
-        tokio::time::sleep(Duration::from_millis(500)).await;
-        
-        Ok(())
+
+    /// Messages the peer sent over the direct channel. Messages the peer
+    /// sends over the relay arrive through the relay connection as before.
+    pub async fn take_incoming(&self) -> Option<mpsc::Receiver<Vec<u8>>> {
+        self.inner.incoming_rx.lock().await.take()
     }
-    
-    async fn connect_relay_only(&self) -> Result<()> {
-        info!("Establishing relay connection (ScreenConnect-style)");
-        
-        self.ensure_relay_connection().await?;
-        
-        info!("Relay connection established");
-        Ok(())
+
+    /// Try to reach the peer directly, settling on the relay if that fails
+    pub async fn connect(&self) -> Result<ConnectionType> {
+        let settings = &self.inner.settings;
+        if settings.force_relay || !settings.prefer_p2p {
+            info!("Using relay for session {}", self.inner.session_id);
+            return Ok(ConnectionType::Relay);
+        }
+
+        match self.try_direct().await {
+            Ok(()) => Ok(ConnectionType::Direct),
+            Err(e) if settings.allow_relay_fallback => {
+                warn!("Direct connection for session {} failed, staying on relay: {}", self.inner.session_id, e);
+                Ok(ConnectionType::Relay)
+            }
+            Err(e) => Err(e),
+        }
     }
-    
-    async fn ensure_relay_connection(&self) -> Result<()> {
-        let relay_guard = self.relay_connection.lock().await;
-        
-        if relay_guard.is_none() {
-            // Create relay connection using existing RelayConnection
-            // TODO: Pass proper ClientConfig
-            info!("Creating new relay connection");
-            // For now, we'll assume the connection is created elsewhere
-            // In a real implementation, we'd create it here with proper config
+
+    async fn try_direct(&self) -> Result<()> {
+        let p2p = self.p2p_manager().await?;
+        let (tx, rx) = oneshot::channel();
+        *self.inner.pending_response.lock().await = Some(tx);
+
+        self.inner.relay.send_message(RelayMessage::P2PHandshake {
+            session_id: self.inner.session_id.clone(),
+            connection_info: p2p.get_local_info().clone(),
+        }).await?;
+
+        let p2p_timeout = self.inner.settings.p2p_timeout;
+        let peer = match timeout(p2p_timeout, rx).await {
+            Ok(Ok(Some(peer))) => peer,
+            Ok(Ok(None)) => return Err(GhostLinkError::Other("Peer declined a direct connection".to_string())),
+            _ => {
+                self.inner.pending_response.lock().await.take();
+                return Err(GhostLinkError::Other(format!("No P2P response within {:?}", p2p_timeout)));
+            }
+        };
+        if !p2p.can_reach(&peer) {
+            return Err(GhostLinkError::Other(format!("NAT types {:?} and {:?} cannot be punched", p2p.get_local_info().nat_type, peer.nat_type)));
         }
-        
+
+        let channel = p2p.connect(&peer, p2p_timeout).await?;
+        self.activate(channel).await;
         Ok(())
     }
-    
-    /// Send data through the active connection
-    pub async fn send_data(&self, data: Vec<u8>) -> Result<()> {
-        let conn_type = self.connection_type.read().await;
-        
-        match *conn_type {
-            ConnectionType::Direct => {
-                self.send_via_p2p(data).await
-            }
-            ConnectionType::Relay => {
-                self.send_via_relay(data).await
-            }
-            ConnectionType::Hybrid => {
-                // Try P2P first, fallback to relay
-                match self.send_via_p2p(data.clone()).await {
-                    Ok(()) => Ok(()),
-                    Err(_) => self.send_via_relay(data).await,
+
+    async fn p2p_manager(&self) -> Result<P2PManager> {
+        let mut stun_servers: Vec<SocketAddr> = Vec::new();
+        for server in &self.inner.settings.stun_servers {
+            match tokio::net::lookup_host(server.as_str()).await {
+                // One address per family, for a public mapping in each
+                Ok(addrs) => {
+                    let addrs: Vec<SocketAddr> = addrs.collect();
+                    stun_servers.extend(addrs.iter().find(|addr| addr.is_ipv4()));
+                    stun_servers.extend(addrs.iter().find(|addr| addr.is_ipv6()));
                 }
+                Err(e) => debug!("Could not resolve STUN server {}: {}", server, e),
             }
         }
+        let p2p = P2PManager::new(self.inner.session_id.clone(), &stun_servers).await?;
+        Ok(p2p.with_tailnet(crate::network::tailnet().await))
     }
-    
-    async fn send_via_p2p(&self, data: Vec<u8>) -> Result<()> {
-        debug!("Sending {} bytes via P2P", data.len());
-        
-        // TODO: Implement actual P2P data sending
-        // This would use the TcpStream from P2P connection
-        
-        Ok(())
-    }
-    
-    async fn send_via_relay(&self, data: Vec<u8>) -> Result<()> {
-        debug!("Sending {} bytes via relay", data.len());
-        
-        let relay_guard = self.relay_connection.lock().await;
-        if let Some(ref relay) = *relay_guard {
-            relay.send_binary_frame(data).await?;
+
+    /// Answer the peer's `P2PHandshake` and start punching towards it
+    pub async fn handle_p2p_handshake(&self, peer: P2PConnectionInfo) -> Result<()> {
+        info!("Received P2P handshake for session {}", self.inner.session_id);
+
+        let settings = &self.inner.settings;
+        let p2p = if settings.force_relay || !settings.prefer_p2p {
+            None
         } else {
-            return Err(anyhow::anyhow!("No relay connection available"));
+            match self.p2p_manager().await {
+                Ok(p2p) if p2p.can_reach(&peer) => Some(p2p),
+                Ok(_) => {
+                    info!("Peer NAT {:?} cannot be punched from here, declining", peer.nat_type);
+                    None
+                }
+                Err(e) => {
+                    warn!("Could not prepare a direct connection: {}", e);
+                    None
+                }
+            }
+        };
+
+        self.inner.relay.send_message(RelayMessage::P2PResponse {
+            session_id: self.inner.session_id.clone(),
+            accepted: p2p.is_some(),
+            connection_info: p2p.as_ref().map(|p2p| p2p.get_local_info().clone()),
+        }).await?;
+
+        if let Some(p2p) = p2p {
+            let manager = self.clone();
+            tokio::spawn(async move {
+                match p2p.connect(&peer, manager.inner.settings.p2p_timeout).await {
+                    Ok(channel) => manager.activate(channel).await,
+                    Err(e) => info!("Direct connection for session {} not established: {}", manager.inner.session_id, e),
+                }
+            });
         }
-        
+
         Ok(())
     }
-    
-    /// Check connection health and switch if needed
-    pub async fn health_check(&self) -> Result<()> {
-        let conn_type = self.connection_type.read().await;
-        
-        match *conn_type {
-            ConnectionType::Direct => {
-                if !self.is_p2p_healthy().await {
-                    warn!("P2P connection unhealthy, switching to relay");
-                    self.switch_to_relay().await?;
-                }
+
+    /// Complete a pending `connect` with the peer's `P2PResponse`
+    pub async fn handle_p2p_response(&self, accepted: bool, connection_info: Option<P2PConnectionInfo>) {
+        match self.inner.pending_response.lock().await.take() {
+            Some(tx) => {
+                let _ = tx.send(connection_info.filter(|_| accepted));
             }
-            ConnectionType::Relay => {
-                if !self.is_relay_healthy().await {
-                    if self.settings.prefer_p2p {
-                        info!("Relay unhealthy, attempting P2P reconnection");
-                        // TODO: Implement P2P reconnection
+            None => debug!("Unexpected P2P response for session {}", self.inner.session_id),
+        }
+    }
+
+    /// Move traffic onto a freshly punched channel
+    async fn activate(&self, channel: DirectChannel) {
+        let channel = Arc::new(channel);
+        *self.inner.direct.write().await = Some(channel.clone());
+        *self.inner.connection_type.write().await = ConnectionType::Direct;
+        info!("Session {} is now direct via {}", self.inner.session_id, channel.remote_addr());
+
+        let manager = self.clone();
+        let receiver = channel.clone();
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => {
+                        if manager.inner.incoming_tx.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        manager.demote(&receiver, &e.to_string()).await;
+                        break;
                     }
                 }
             }
-            ConnectionType::Hybrid => {
-                // Check both connections
-                let p2p_healthy = self.is_p2p_healthy().await;
-                let relay_healthy = self.is_relay_healthy().await;
-                
-                if !p2p_healthy && !relay_healthy {
-                    return Err(anyhow::anyhow!("All connections unhealthy"));
+        });
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !manager.is_current(&channel).await {
+                    break;
+                }
+                if channel.idle_for() > manager.inner.settings.idle_timeout {
+                    manager.demote(&channel, "peer went quiet").await;
+                    break;
+                }
+                if let Err(e) = channel.keepalive().await {
+                    manager.demote(&channel, &e.to_string()).await;
+                    break;
                 }
             }
+        });
+    }
+
+    async fn is_current(&self, channel: &Arc<DirectChannel>) -> bool {
+        self.inner.direct.read().await.as_ref().is_some_and(|current| Arc::ptr_eq(current, channel))
+    }
+
+    /// Fall back to the relay if `channel` is still the active one
+    async fn demote(&self, channel: &Arc<DirectChannel>, reason: &str) {
+        let mut direct = self.inner.direct.write().await;
+        if direct.as_ref().is_some_and(|current| Arc::ptr_eq(current, channel)) {
+            *direct = None;
+            *self.inner.connection_type.write().await = self.relayed_type().await;
+            warn!("Direct channel for session {} lost ({}), back on relay", self.inner.session_id, reason);
         }
-        
-        Ok(())
     }
-    
-    async fn is_p2p_healthy(&self) -> bool {
-        let p2p_guard = self.p2p_manager.lock().await;
-        if let Some(ref p2p) = *p2p_guard {
-            p2p.is_p2p_capable()
+
+    /// How traffic goes while there is no direct channel
+    async fn relayed_type(&self) -> ConnectionType {
+        if self.inner.udp_lane.read().await.is_some() {
+            ConnectionType::RelayUdp
         } else {
-            false
+            ConnectionType::Relay
         }
     }
-    
-    async fn is_relay_healthy(&self) -> bool {
-        let relay_guard = self.relay_connection.lock().await;
-        if let Some(ref relay) = *relay_guard {
-            relay.is_healthy().await
-        } else {
-            false
+
+    /// Move frames onto the relay's UDP lane while it answers keepalives
+    pub async fn activate_udp_lane(&self, lane: UdpLane) {
+        let lane = Arc::new(lane);
+        let keepalive_interval = lane.timings().keepalive_interval;
+        *self.inner.udp_lane.write().await = Some(lane.clone());
+        if self.inner.direct.read().await.is_none() {
+            *self.inner.connection_type.write().await = ConnectionType::RelayUdp;
         }
+        info!("Frames of session {} now take the relay's UDP lane", self.inner.session_id);
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(keepalive_interval);
+            loop {
+                interval.tick().await;
+                if !manager.inner.udp_lane.read().await.as_ref().is_some_and(|current| Arc::ptr_eq(current, &lane)) {
+                    break;
+                }
+                if lane.status() == LaneStatus::Failed {
+                    manager.drop_udp_lane(&lane, "relay went quiet", true).await;
+                    break;
+                }
+                if let Err(e) = lane.keepalive().await {
+                    manager.drop_udp_lane(&lane, &e.to_string(), true).await;
+                    break;
+                }
+            }
+        });
     }
-    
-    async fn switch_to_relay(&self) -> Result<()> {
-        info!("Switching from P2P to relay connection");
-        
-        self.ensure_relay_connection().await?;
-        
-        let mut conn_type = self.connection_type.write().await;
-        *conn_type = ConnectionType::Relay;
-        
-        Ok(())
+
+    /// The relay closed the session's UDP lane
+    pub async fn close_udp_lane(&self, reason: &str) {
+        let lane = self.inner.udp_lane.read().await.clone();
+        if let Some(lane) = lane {
+            self.drop_udp_lane(&lane, reason, false).await;
+        }
     }
-    
-    /// Handle incoming P2P handshake from peer
-    pub async fn handle_p2p_handshake(&self, _connection_info: P2PConnectionInfo) -> Result<()> {
-        info!("Received P2P handshake from peer");
-        
-        // Initialize our P2P manager if not already done
-        if self.p2p_manager.lock().await.is_none() {
-            let p2p_manager = P2PManager::new(
-                self.session_id.clone(),
-                self.relay_servers.clone(),
-                self.rendezvous_server,
-            ).await?;
-            
-            let mut p2p_guard = self.p2p_manager.lock().await;
-            *p2p_guard = Some(p2p_manager);
+
+    /// Put frames back on the WebSocket if `lane` is still the active one,
+    /// telling the relay unless it closed the lane itself
+    async fn drop_udp_lane(&self, lane: &Arc<UdpLane>, reason: &str, notify_relay: bool) {
+        {
+            let mut current = self.inner.udp_lane.write().await;
+            if !current.as_ref().is_some_and(|current| Arc::ptr_eq(current, lane)) {
+                return;
+            }
+            *current = None;
+        }
+        let mut connection_type = self.inner.connection_type.write().await;
+        if *connection_type == ConnectionType::RelayUdp {
+            *connection_type = ConnectionType::Relay;
         }
-        
-        // Respond with our connection info
-        let our_info = {
-            let p2p_guard = self.p2p_manager.lock().await;
-            if let Some(ref p2p) = *p2p_guard {
-                p2p.get_local_info().clone()
-            } else {
-                return Err(anyhow::anyhow!("P2P manager not available"));
+        drop(connection_type);
+        warn!("UDP lane of session {} lost ({}), frames back on the WebSocket", self.inner.session_id, reason);
+
+        if notify_relay {
+            let closed = RelayMessage::UdpLaneClosed {
+                session_id: self.inner.session_id.clone(),
+                reason: Some(reason.to_string()),
+            };
+            if let Err(e) = self.inner.relay.send_message(closed).await {
+                debug!("Failed to tell the relay about the lost UDP lane: {}", e);
             }
-        };
-        
-        let response = RelayMessage::P2PResponse {
-            session_id: self.session_id.clone(),
-            accepted: true,
-            connection_info: Some(our_info),
-        };
-        
-        let relay_guard = self.relay_connection.lock().await;
-        if let Some(ref relay) = *relay_guard {
-            relay.send_message(response).await?;
         }
-        
-        // Attempt direct P2P connection
-        // TODO: Use connection_info to establish direct connection
-        
-        Ok(())
     }
-    
+
+    /// Send a protocol envelope over the direct channel, or the relay in the
+    /// given lane
+    pub async fn send(&self, envelope: Vec<u8>, priority: MessagePriority) -> Result<()> {
+        let direct = self.inner.direct.read().await.clone();
+        if let Some(channel) = direct {
+            match channel.send(&envelope).await {
+                Ok(()) => return Ok(()),
+                Err(e) => self.demote(&channel, &e.to_string()).await,
+            }
+        }
+        self.inner.relay.send_binary(envelope, priority).await
+    }
+
+    /// Send an encoded screen frame envelope, over the UDP lane when there
+    /// is one and no direct channel
+    pub async fn send_frame(&self, envelope: Vec<u8>, keyframe: bool) -> Result<()> {
+        if self.inner.direct.read().await.is_none() && envelope.len() <= datagram::MAX_FRAME_LEN {
+            let lane = self.inner.udp_lane.read().await.clone();
+            if let Some(lane) = lane {
+                match lane.send_frame(&envelope, keyframe).await {
+                    Ok(()) => return Ok(()),
+                    Err(e) => self.drop_udp_lane(&lane, &e.to_string(), true).await,
+                }
+            }
+        }
+        self.send(envelope, MessagePriority::Normal).await
+    }
+
+    /// Send an encoded input batch envelope
+    pub async fn send_input(&self, envelope: Vec<u8>) -> Result<()> {
+        self.send(envelope, MessagePriority::High).await
+    }
+
     /// Get current connection statistics
     pub async fn get_connection_stats(&self) -> ConnectionStats {
-        let conn_type = self.connection_type.read().await;
-        
+        let direct = self.inner.direct.read().await.clone();
+
         ConnectionStats {
-            connection_type: conn_type.clone(),
-            p2p_available: self.is_p2p_healthy().await,
-            relay_available: self.is_relay_healthy().await,
-            session_id: self.session_id.clone(),
+            connection_type: self.connection_type().await,
+            direct_remote: direct.map(|channel| channel.remote_addr()),
+            relay_available: self.inner.relay.is_healthy().await,
+            session_id: self.inner.session_id.clone(),
         }
     }
-    
-    /// Disconnect all connections
-    pub async fn disconnect(&self) -> Result<()> {
+
+    /// Close the direct channel and UDP lane; the relay is left to its owner
+    pub async fn disconnect(&self) {
         info!("Disconnecting hybrid connection manager");
-        
-        // Disconnect relay
-        let relay_guard = self.relay_connection.lock().await;
-        if let Some(ref relay) = *relay_guard {
-            relay.disconnect().await?;
+
+        if let Some(channel) = self.inner.direct.write().await.take() {
+            channel.close().await;
         }
-        
-        // Disconnect P2P
-        // TODO: Implement P2P disconnection
-        
-        info!("All connections disconnected");
-        Ok(())
+        self.inner.udp_lane.write().await.take();
+        *self.inner.connection_type.write().await = ConnectionType::Relay;
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ConnectionStats {
     pub connection_type: ConnectionType,
-    pub p2p_available: bool,
+    pub direct_remote: Option<SocketAddr>,
     pub relay_available: bool,
     pub session_id: String,
 }
@@ -388,28 +488,186 @@ pub struct ConnectionStats {
 #[cfg(test)]
 mod tests {
     use super::*;
-    
-    #[tokio::test]
-    async fn test_hybrid_connection_creation() {
-        let relay_servers = vec!["127.0.0.1:8080".parse().unwrap()];
-        let rendezvous_server = "127.0.0.1:8081".parse().unwrap();
-        let settings = ConnectionSettings::default();
-        
-        let manager = HybridConnectionManager::new(
-            "test_session".to_string(),
-            relay_servers,
-            rendezvous_server,
-            settings,
-        ).await;
-        
-        assert!(manager.is_ok());
-    }
-    
+    use crate::connection::datagram::LaneTimings;
+    use crate::connection::stun::tests::spawn_stun_server;
+    use crate::connection::udp_lane::tests::spawn_lane_relay;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex as StdMutex;
+
+    /// Relay stand-in that hands P2P messages to the other side and records
+    /// everything sent as binary
+    struct LoopbackRelay {
+        to_peer: mpsc::UnboundedSender<RelayMessage>,
+        binary: StdMutex<Vec<(Vec<u8>, MessagePriority)>>,
+    }
+
+    #[async_trait]
+    impl RelayLink for LoopbackRelay {
+        async fn send_message(&self, message: RelayMessage) -> Result<()> {
+            let _ = self.to_peer.send(message);
+            Ok(())
+        }
+
+        async fn send_binary(&self, data: Vec<u8>, priority: MessagePriority) -> Result<()> {
+            self.binary.lock().unwrap().push((data, priority));
+            Ok(())
+        }
+
+        async fn is_healthy(&self) -> bool {
+            true
+        }
+    }
+
+    fn loopback() -> (Arc<LoopbackRelay>, mpsc::UnboundedReceiver<RelayMessage>) {
+        let (to_peer, from_us) = mpsc::unbounded_channel();
+        (Arc::new(LoopbackRelay { to_peer, binary: StdMutex::new(Vec::new()) }), from_us)
+    }
+
+    /// Deliver P2P messages the way the agent and viewer would
+    fn deliver(mut messages: mpsc::UnboundedReceiver<RelayMessage>, peer: HybridConnectionManager) {
+        tokio::spawn(async move {
+            while let Some(message) = messages.recv().await {
+                match message {
+                    RelayMessage::P2PHandshake { connection_info, .. } => {
+                        peer.handle_p2p_handshake(connection_info).await.unwrap();
+                    }
+                    RelayMessage::P2PResponse { accepted, connection_info, .. } => {
+                        peer.handle_p2p_response(accepted, connection_info).await;
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    fn settings(stun: SocketAddr) -> ConnectionSettings {
+        ConnectionSettings {
+            stun_servers: vec![stun.to_string()],
+            ..ConnectionSettings::default()
+        }
+    }
+
     #[test]
     fn test_connection_settings_default() {
         let settings = ConnectionSettings::default();
         assert!(settings.prefer_p2p);
         assert!(settings.allow_relay_fallback);
         assert!(settings.encryption_required);
+        assert!(!settings.stun_servers.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_connection_type_display() {
+        assert_eq!(ConnectionType::Direct.to_string(), "Direct");
+        assert_eq!(ConnectionType::Relay.to_string(), "Relayed");
+        assert_eq!(ConnectionType::RelayUdp.to_string(), "Relayed (UDP)");
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_connection_creation() {
+        let (relay, _) = loopback();
+        let manager = HybridConnectionManager::new("test_session".to_string(), relay, ConnectionSettings::default());
+
+        assert_eq!(manager.connection_type().await, ConnectionType::Relay);
+        assert!(manager.take_incoming().await.is_some());
+        assert!(manager.take_incoming().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_goes_direct_and_carries_traffic() {
+        let stun = spawn_stun_server().await;
+        let (viewer_relay, to_agent) = loopback();
+        let (agent_relay, to_viewer) = loopback();
+        let viewer = HybridConnectionManager::new("session".to_string(), viewer_relay.clone(), settings(stun));
+        let agent = HybridConnectionManager::new("session".to_string(), agent_relay.clone(), settings(stun));
+        deliver(to_agent, agent.clone());
+        deliver(to_viewer, viewer.clone());
+
+        assert_eq!(viewer.connect().await.unwrap(), ConnectionType::Direct);
+        let mut agent_incoming = agent.take_incoming().await.unwrap();
+        let mut viewer_incoming = viewer.take_incoming().await.unwrap();
+
+        // The responder finishes punching on its own task
+        for _ in 0..50 {
+            if agent.connection_type().await == ConnectionType::Direct {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(agent.connection_type().await, ConnectionType::Direct);
+        assert!(viewer.get_connection_stats().await.direct_remote.is_some());
+
+        let frame = vec![7u8; 5000];
+        agent.send_frame(frame.clone(), true).await.unwrap();
+        viewer.send_input(b"input batch".to_vec()).await.unwrap();
+
+        let received = timeout(Duration::from_secs(5), viewer_incoming.recv()).await.unwrap();
+        assert_eq!(received, Some(frame));
+        let received = timeout(Duration::from_secs(5), agent_incoming.recv()).await.unwrap();
+        assert_eq!(received, Some(b"input batch".to_vec()));
+
+        // Nothing went through the relay once direct
+        assert!(agent_relay.binary.lock().unwrap().is_empty());
+        assert!(viewer_relay.binary.lock().unwrap().is_empty());
+
+        // Closing one end moves the other back to the relay
+        viewer.disconnect().await;
+        for _ in 0..50 {
+            if agent.connection_type().await == ConnectionType::Relay {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(agent.connection_type().await, ConnectionType::Relay);
+        agent.send_frame(vec![1], false).await.unwrap();
+        assert_eq!(agent_relay.binary.lock().unwrap().as_slice(), &[(vec![1], MessagePriority::Normal)]);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_relay_when_peer_is_silent() {
+        let stun = spawn_stun_server().await;
+        let (relay, _unanswered) = loopback();
+        let settings = ConnectionSettings {
+            p2p_timeout: Duration::from_millis(300),
+            ..settings(stun)
+        };
+        let manager = HybridConnectionManager::new("session".to_string(), relay.clone(), settings);
+
+        let result = timeout(Duration::from_secs(5), manager.connect()).await.unwrap();
+        assert_eq!(result.unwrap(), ConnectionType::Relay);
+        assert_eq!(manager.connection_type().await, ConnectionType::Relay);
+
+        manager.send_input(vec![9]).await.unwrap();
+        assert_eq!(relay.binary.lock().unwrap().as_slice(), &[(vec![9], MessagePriority::High)]);
+    }
+
+    #[tokio::test]
+    async fn test_frames_take_udp_lane_until_it_goes_quiet() {
+        let answering = Arc::new(AtomicBool::new(true));
+        let (lane_relay, mut lane_frames) = spawn_lane_relay(answering.clone(), 0).await;
+        let (relay, mut messages) = loopback();
+        let manager = HybridConnectionManager::new("session".to_string(), relay.clone(), ConnectionSettings::default());
+
+        let timings = LaneTimings {
+            probe_window: Duration::from_millis(600),
+            keepalive_interval: Duration::from_millis(50),
+            silence_timeout: Duration::from_millis(300),
+        };
+        let lane = UdpLane::connect(lane_relay, [4; 16], timings).await.unwrap();
+        manager.activate_udp_lane(lane).await;
+        assert_eq!(manager.connection_type().await, ConnectionType::RelayUdp);
+
+        manager.send_frame(vec![5u8; 4000], true).await.unwrap();
+        let received = timeout(Duration::from_secs(2), lane_frames.recv()).await.unwrap();
+        assert_eq!(received, Some(vec![5u8; 4000]));
+        assert!(relay.binary.lock().unwrap().is_empty());
+
+        // UDP stops getting through: the relay hears about it and frames return to the WebSocket
+        answering.store(false, Ordering::SeqCst);
+        let closed = timeout(Duration::from_secs(2), messages.recv()).await.unwrap();
+        assert!(matches!(closed, Some(RelayMessage::UdpLaneClosed { ref session_id, .. }) if session_id == "session"));
+        assert_eq!(manager.connection_type().await, ConnectionType::Relay);
+        manager.send_frame(vec![6], false).await.unwrap();
+        assert_eq!(relay.binary.lock().unwrap().as_slice(), &[(vec![6], MessagePriority::Normal)]);
+    }
+}
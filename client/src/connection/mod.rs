@@ -1,35 +1,102 @@
 #![allow(dead_code)]
 
-use anyhow::{Context, Result};
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, RwLock};
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
 use tracing::{debug, error, info, warn, trace};
 use url::Url;
+use uuid::Uuid;
 
+use crate::agent::capabilities::AgentCapability;
 use crate::agent::heartbeat::{HeartbeatManager, HeartbeatMessage};
+use crate::agent::inventory::Inventory;
+use crate::capture::cursor::CursorUpdate;
+use crate::capture::encoder_profile::EncoderProfile;
+use crate::capture::negotiation::{StreamConfig, ViewerCapabilities};
+use crate::chat::{ChatParty, ReceiptStatus};
 use crate::config::ClientConfig;
+use crate::elevation::{ElevationLevel, ElevationMethod};
+use crate::error::{ConnectionError, ConnectionErrorKind, Context, GhostLinkError, Result};
+use crate::file_transfer::browser::{FsError, FsResponse};
+use crate::input::{InputEvent, InputMode};
+use crate::network::{self, NetworkInterface, TailnetAddress};
+use crate::policy::ServerPolicy;
+use crate::registry::{RegistryAuditEntry, RegistryOperation};
+use crate::session::banner::{SessionBanner, SessionCancelReason};
+use crate::session::watchdog::HealthEvent;
+use crate::session::preflight::FailureCode;
+use crate::session::SessionReadiness;
+use crate::toolbox::queue::{ToolJob, ToolJobResult};
+use compression::{CompressionCounters, CompressionReport};
+use protocol::{Envelope, FileChunkBody, MessageKind, ScreenFrameBody};
 
 // pub mod auth;
 // pub mod reconnect;
 pub mod p2p;
 pub mod hybrid;
+pub mod datagram;
+pub mod udp_lane;
+pub mod enrollment;
 pub mod monitor_protocol;
+pub mod compression;
+pub mod outbound;
+pub mod protocol;
+pub mod proxy;
+pub mod quality;
+pub mod stun;
+pub mod tls;
 
 pub use p2p::{P2PManager, P2PConnectionInfo};
+pub use outbound::{MessagePriority, OutboundStats};
 
 pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+pub type WsSink = SplitSink<WsStream, Message>;
+
+/// How long a disconnect waits for queued messages and the close frame to go out
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// WebSocket connection to AtlasConnect server
 pub struct RelayConnection {
     config: ClientConfig,
-    ws_stream: Arc<RwLock<Option<WsStream>>>,
+    /// Sent with every registration, reconnects included
+    capabilities: Vec<AgentCapability>,
+    /// Prioritised queue drained into the socket by the writer task
+    outbound: RwLock<Option<outbound::OutboundSender>>,
+    /// Writer task for the current socket, awaited on disconnect
+    writer: RwLock<Option<JoinHandle<()>>>,
     heartbeat_manager: Arc<RwLock<HeartbeatManager>>,
+    /// Envelope version agreed with the relay; 0 until it accepts, which keeps bulk traffic on JSON
+    binary_version: Arc<AtomicU8>,
+    /// The relay agreed to compressed JSON; false until it does, see `compression`
+    compression: Arc<AtomicBool>,
+    /// What compressing JSON to the relay saved
+    text_compression: CompressionCounters,
+    /// Frame compression of the sessions that asked for it
+    frame_compression: Mutex<HashMap<String, FrameCompression>>,
+    /// Server messages that need agent-level handling (sessions, etc.)
     message_tx: mpsc::Sender<RelayMessage>,
-    message_rx: mpsc::Receiver<RelayMessage>,
+    message_rx: Option<mpsc::Receiver<RelayMessage>>,
+    /// Payloads of the pongs the relay sends back, for [`Self::round_trip`]
+    pongs: broadcast::Sender<Vec<u8>>,
+}
+
+/// Frame compression of one session
+#[derive(Debug, Default)]
+struct FrameCompression {
+    /// Frames are compressed right now
+    active: bool,
+    counters: CompressionCounters,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,7 +107,44 @@ pub enum RelayMessage {
         agent_id: String,
         hostname: String,
         os_info: serde_json::Value,
-        capabilities: Vec<String>,
+        capabilities: Vec<AgentCapability>,
+        /// Unix time the registration was signed at
+        #[serde(default)]
+        timestamp: i64,
+        /// Signature from the enrolled device key, see `enrollment`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+        /// Binary envelope versions this agent speaks, see `protocol`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        binary_protocol: Vec<u8>,
+        /// Compression codecs this agent speaks, see `compression`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        compression: Vec<String>,
+        /// Network adapters, so the server can wake this machine through a neighbour
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        interfaces: Vec<NetworkInterface>,
+        /// Tailnet address, so viewers on the same tailnet connect over it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tailnet: Option<TailnetAddress>,
+        /// Support code a temporary agent joins with instead of signing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        support_code: Option<String>,
+    },
+    
+    // Sent on shutdown so the relay marks the agent offline right away
+    // instead of waiting for heartbeats to time out. Shares the session
+    // control lane so it can't overtake the SessionEnds sent before it.
+    AgentDeregister {
+        agent_id: String,
+        reason: String,
+    },
+    
+    // Relay's answer to `binary_protocol`: bulk traffic may use envelopes
+    ProtocolAccepted {
+        version: u8,
+        /// Codec the relay picked from our offer; none keeps JSON uncompressed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<String>,
     },
     
     // Authentication
@@ -58,16 +162,64 @@ pub enum RelayMessage {
         session_id: String,
         session_type: String,
         requester: String,
+        /// Record the session; unset defers to the agent's recording config
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        record: Option<bool>,
+        /// What the viewer decodes; older viewers announce nothing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<ViewerCapabilities>,
+        /// Banner to show the end user before the session starts
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        banner: Option<SessionBanner>,
+        /// Ask the end user before starting; the server sets this from policy
+        #[serde(default)]
+        requires_consent: bool,
+        /// Bandwidth cap the technician asked for, already held to policy
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bandwidth_kbps: Option<u32>,
+        /// Compress frames while the link is slower than the encoder's target
+        #[serde(default)]
+        compress_frames: bool,
     },
     
     SessionResponse {
         session_id: String,
         accepted: bool,
         reason: Option<String>,
+        /// Pre-flight report of a session that was created; the failed
+        /// check is why one was not
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        readiness: Option<SessionReadiness>,
+        /// Set when the viewer can walk the technician through a fix
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error_code: Option<FailureCode>,
+    },
+    
+    // The end user accepted the session's banner
+    BannerAcknowledged {
+        session_id: String,
+        banner_id: String,
+    },
+    
+    // The agent called off a session before it started
+    SessionCancelled {
+        session_id: String,
+        reason: SessionCancelReason,
     },
     
     SessionEnd {
         session_id: String,
+        /// Why the agent ended it, when it had to; the relay records the
+        /// session as failed and tells the viewer
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error_code: Option<FailureCode>,
+    },
+    
+    // The technician asked to keep an idle session open
+    SessionKeepalive {
+        session_id: String,
     },
     
     // Screen capture
@@ -87,8 +239,23 @@ pub enum RelayMessage {
     },
     
     // File transfer
+    FileTransferStart {
+        session_id: String,
+        transfer_id: String,
+        filename: String,
+        total_size: u64,
+        total_chunks: u32,
+        chunk_size: u32,
+        sha256: String,
+        // Dropped onto the viewer: saved to the drop directory
+        #[serde(default)]
+        drop: bool,
+    },
+    
     FileTransfer {
         session_id: String,
+        #[serde(default)]
+        transfer_id: String,
         file_data: Vec<u8>,
         filename: String,
         total_size: u64,
@@ -96,6 +263,79 @@ pub enum RelayMessage {
         total_chunks: u32,
     },
     
+    // Receiver's answer to a start: the first chunk it still needs
+    FileTransferResume {
+        session_id: String,
+        transfer_id: String,
+        next_chunk: u32,
+    },
+    
+    FileTransferComplete {
+        session_id: String,
+        transfer_id: String,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    FileTransferCancel {
+        session_id: String,
+        transfer_id: String,
+        reason: Option<String>,
+    },
+    
+    // Technician asks the agent to send a file
+    FileTransferRequest {
+        session_id: String,
+        path: String,
+    },
+    
+    // File browser, answered by FsResult
+    ListDirectory {
+        session_id: String,
+        request_id: String,
+        #[serde(default)]
+        path: String,
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    
+    StatEntry {
+        session_id: String,
+        request_id: String,
+        path: String,
+    },
+    
+    CreateDirectory {
+        session_id: String,
+        request_id: String,
+        path: String,
+    },
+    
+    Rename {
+        session_id: String,
+        request_id: String,
+        from: String,
+        to: String,
+    },
+    
+    Delete {
+        session_id: String,
+        request_id: String,
+        path: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    
+    FsResult {
+        session_id: String,
+        request_id: String,
+        success: bool,
+        result: Option<FsResponse>,
+        error: Option<FsError>,
+    },
+    
     // Monitor control
     MonitorControl {
         session_id: String,
@@ -115,12 +355,350 @@ pub enum RelayMessage {
         connection_info: Option<P2PConnectionInfo>,
     },
     
+    // The relay takes this session's frames on its UDP port; see `udp_lane`
+    UdpLaneOffer {
+        session_id: String,
+        port: u16,
+        /// Association token, base64
+        token: String,
+    },
+    
+    // Either end gave up on the UDP lane; frames go back to the WebSocket
+    UdpLaneClosed {
+        session_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    
+    // Archive collection (tar.zst of a remote directory)
+    CollectArchive {
+        session_id: String,
+        request_id: String,
+        path: String,
+        #[serde(default)]
+        include_globs: Vec<String>,
+        max_size: u64,
+    },
+    
+    ArchiveError {
+        request_id: String,
+        message: String,
+    },
+    
+    // Registry editing (Windows agents)
+    RegistryRequest {
+        session_id: String,
+        request_id: String,
+        operation: RegistryOperation,
+    },
+    
+    RegistryResult {
+        request_id: String,
+        success: bool,
+        result: Option<serde_json::Value>,
+        error: Option<String>,
+        audit: Option<RegistryAuditEntry>,
+    },
+    
+    // Privilege elevation approved through the server's PAM flow
+    ElevationRequest {
+        session_id: String,
+        level: ElevationLevel,
+    },
+    
+    ElevationResult {
+        session_id: String,
+        level: ElevationLevel,
+        success: bool,
+        /// Refused by the platform or the user, as opposed to failing
+        #[serde(default)]
+        denied: bool,
+        method: Option<ElevationMethod>,
+        error: Option<String>,
+    },
+    
+    // Encoder profile switch from the technician's quality command
+    SetEncoderProfile {
+        session_id: String,
+        profile: EncoderProfile,
+    },
+    
+    // Quality setting (0-100) from the technician, caps adaptive streaming
+    SetQuality {
+        session_id: String,
+        quality: u8,
+    },
+    
+    // Bandwidth cap from the technician, held to the policy ceiling by the
+    // server; `None` lifts it
+    SetBandwidthCap {
+        session_id: String,
+        #[serde(default)]
+        kbps: Option<u32>,
+    },
+    
+    // A viewer joined mid-stream or lost its reference frame
+    RequestKeyframe {
+        session_id: String,
+    },
+    
+    // Codec of the screen stream, sent before its first frame and again
+    // whenever it changes
+    StreamConfig {
+        session_id: String,
+        config: StreamConfig,
+    },
+    
+    // The viewer cannot decode the stream; step down to a simpler codec
+    StreamDowngrade {
+        session_id: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    
+    // Cursor position, and shape when it changed, independent of frames
+    CursorUpdate {
+        session_id: String,
+        cursor: CursorUpdate,
+    },
+    
+    // The viewer turns cursor updates on or off
+    SetCursorStreaming {
+        session_id: String,
+        enabled: bool,
+    },
+    
+    // The viewer asks for absolute or relative pointer input
+    SetInputMode {
+        session_id: String,
+        mode: InputMode,
+    },
+    
+    // The input mode now applied, answering `SetInputMode`; `reason` says
+    // why a request was not granted
+    InputModeChanged {
+        session_id: String,
+        mode: InputMode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    
+    // The focused app grabbed or released the pointer
+    InputModeHint {
+        session_id: String,
+        mode: InputMode,
+        reason: String,
+    },
+    
+    // The viewer turns input preview on or off: events are validated but
+    // not injected
+    SetInputPreview {
+        session_id: String,
+        enabled: bool,
+    },
+    
+    // Whether input preview is on now, answering `SetInputPreview`
+    InputPreviewChanged {
+        session_id: String,
+        enabled: bool,
+    },
+    
+    // An event validated in preview mode, acknowledged to the viewer
+    InputPreviewed {
+        session_id: String,
+        event: InputEvent,
+    },
+    
+    // The session was handed to another technician; it carries on as before
+    SessionOperatorChanged {
+        session_id: String,
+        requester: String,
+    },
+    
     // Clipboard sync (RustDesk feature)
     ClipboardSync {
         session_id: String,
         content: String,
         content_type: String,
     },
+    // Link quality: either side probes, the other echoes with `echo` set
+    LatencyProbe {
+        session_id: String,
+        sequence: u32,
+        /// Sender's clock when the probe left, in ms since the epoch
+        timestamp: i64,
+        #[serde(default)]
+        echo: bool,
+    },
+    
+    QualityReport {
+        session_id: String,
+        report: quality::QualityReport,
+    },
+    
+    // A step of the session watchdog restarting capture, encoder or input
+    SessionHealth {
+        session_id: String,
+        #[serde(flatten)]
+        event: HealthEvent,
+    },
+    
+    // Remote terminal, see `terminal`
+    TerminalOpen {
+        session_id: String,
+        /// Shell to run; unset uses the agent's default
+        #[serde(default)]
+        shell: Option<String>,
+        #[serde(default)]
+        cols: u16,
+        #[serde(default)]
+        rows: u16,
+        #[serde(default)]
+        cwd: Option<String>,
+        /// Variables set on top of the agent's environment
+        #[serde(default)]
+        env: HashMap<String, String>,
+    },
+    
+    TerminalOpened {
+        session_id: String,
+        shell: String,
+        pid: u32,
+    },
+    
+    TerminalInput {
+        session_id: String,
+        data: String,
+    },
+    
+    TerminalOutput {
+        session_id: String,
+        data: Vec<u8>,
+    },
+    
+    TerminalResize {
+        session_id: String,
+        cols: u16,
+        rows: u16,
+    },
+    
+    TerminalClose {
+        session_id: String,
+    },
+    
+    TerminalClosed {
+        session_id: String,
+        exit_code: Option<i32>,
+        /// Why the agent closed it; unset when the shell exited
+        reason: Option<String>,
+    },
+    
+    // Recent output of an open terminal, for the server's output view
+    TerminalScrollbackRequest {
+        session_id: String,
+    },
+    
+    TerminalScrollback {
+        session_id: String,
+        data: Vec<u8>,
+    },
+    
+    // In-session chat, see `chat`
+    ChatMessage {
+        session_id: String,
+        message_id: String,
+        from: ChatParty,
+        /// Display name of the author
+        #[serde(default)]
+        sender: Option<String>,
+        body: String,
+        /// Stamped by the relay
+        #[serde(default)]
+        sent_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    
+    ChatReceipt {
+        session_id: String,
+        message_id: String,
+        status: ReceiptStatus,
+    },
+    
+    // The relay refused a chat message
+    ChatError {
+        session_id: String,
+        message_id: String,
+        error: String,
+    },
+    
+    // The relay accepted the support code this agent joined with
+    SupportCodeAccepted {
+        code: String,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    },
+    
+    // The support code is used up or expired; a temporary agent exits
+    SupportCodeClosed {
+        #[serde(default)]
+        code: String,
+        reason: String,
+    },
+    
+    // The end user typed a support code into the tray; this enrolled agent
+    // joins it and stays enrolled once the session is over
+    JoinSupportCode {
+        code: String,
+    },
+    
+    // The end user paused remote access until `until`, or resumed it
+    AccessPaused {
+        #[serde(default)]
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    
+    // The relay moved input control to another session, or took it away
+    ControlChanged {
+        #[serde(default)]
+        holder: Option<String>,
+    },
+    
+    // The agent's effective policy, sent on registration and when it changes
+    PolicyUpdate {
+        policy: ServerPolicy,
+    },
+    
+    // The server picked this agent to wake a sleeping device on its subnet
+    WakeOnLan {
+        mac: String,
+        /// Directed broadcast address of the shared subnet; unset when the
+        /// devices share only an IPv6 link
+        #[serde(default)]
+        broadcast: Option<Ipv4Addr>,
+    },
+    
+    // A deployment asks this agent to run a tool or script
+    ExecuteTool {
+        #[serde(flatten)]
+        job: ToolJob,
+    },
+    
+    // How a deployment's tool or script ended
+    ToolResult {
+        execution_id: Uuid,
+        deployment_id: Uuid,
+        #[serde(flatten)]
+        result: ToolJobResult,
+    },
+    
+    // The server stored a tool result; the agent can forget it
+    ToolResultAccepted {
+        execution_id: Uuid,
+    },
+    
+    // Hardware and software inventory, sent on registration and daily
+    Inventory {
+        inventory: Inventory,
+    },
+    
     // Control messages
     Ping,
     Pong,
@@ -129,11 +707,34 @@ pub enum RelayMessage {
     Error {
         code: u32,
         message: String,
+        /// Session the error is about; the relay passes it on to its viewers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
     },
 }
 
+impl RelayMessage {
+    /// Outbound lane for this message
+    pub fn priority(&self) -> MessagePriority {
+        match self {
+            RelayMessage::AgentRegister { .. }
+            | RelayMessage::Authenticate { .. }
+            | RelayMessage::ProtocolAccepted { .. }
+            | RelayMessage::Heartbeat { .. }
+            // Probes skip queued frames so they time the link, not the queue
+            | RelayMessage::LatencyProbe { .. }
+            | RelayMessage::Ping
+            | RelayMessage::Pong
+            | RelayMessage::Error { .. } => MessagePriority::Critical,
+            RelayMessage::ScreenFrame { .. } => MessagePriority::Normal,
+            RelayMessage::FileTransfer { .. } | RelayMessage::Inventory { .. } => MessagePriority::Low,
+            _ => MessagePriority::High,
+        }
+    }
+}
+
 impl RelayConnection {
-    pub async fn new(config: &ClientConfig) -> Result<Self> {
+    pub async fn new(config: &ClientConfig, capabilities: Vec<AgentCapability>) -> Result<Self> {
         let (message_tx, message_rx) = mpsc::channel(100);
         
         let heartbeat_manager = Arc::new(RwLock::new(HeartbeatManager::new(
@@ -142,56 +743,85 @@ impl RelayConnection {
         
         let connection = Self {
             config: config.clone(),
-            ws_stream: Arc::new(RwLock::new(None)),
+            capabilities,
+            outbound: RwLock::new(None),
+            writer: RwLock::new(None),
+            binary_version: Arc::new(AtomicU8::new(0)),
+            compression: Arc::new(AtomicBool::new(false)),
+            text_compression: CompressionCounters::default(),
+            frame_compression: Mutex::new(HashMap::new()),
             heartbeat_manager,
             message_tx,
-            message_rx,
+            message_rx: Some(message_rx),
+            pongs: broadcast::channel(8).0,
         };
         
-        connection.connect().await?;
-        connection.start_message_handler().await?;
+        let ws_read = connection.connect().await?;
+        connection.start_message_handler(ws_read).await?;
         
         Ok(connection)
     }
 
+    /// Take the receiver for server messages the agent must act on.
+    ///
+    /// Only the first call returns `Some`; the agent event loop owns it afterwards.
+    pub fn take_message_receiver(&mut self) -> Option<mpsc::Receiver<RelayMessage>> {
+        self.message_rx.take()
+    }
+
     /// Establish WebSocket connection to server
-    async fn connect(&self) -> Result<()> {
-        let url = Url::parse(&self.config.server_url)
-            .context("Invalid server URL")?;
+    ///
+    /// Returns the read half of the socket; the write half is handed to a
+    /// writer task fed by the outbound queue.
+    async fn connect(&self) -> Result<SplitStream<WsStream>> {
+        let url = server_url(&self.config)?;
         
         info!("Connecting to WebSocket: {}", url);
         
-        let (ws_stream, response) = connect_async(&url).await
-            .context("Failed to connect to WebSocket")?;
+        let (ws_stream, response) = tls::connect(&self.config, &url).await?;
         
         info!("WebSocket connected, response: {}", response.status());
         
-        let mut stream_guard = self.ws_stream.write().await;
-        *stream_guard = Some(ws_stream);
+        let (ws_write, ws_read) = ws_stream.split();
+        // A new socket starts on JSON until the relay accepts our offer
+        self.binary_version.store(0, Ordering::SeqCst);
+        self.compression.store(false, Ordering::SeqCst);
+        let (outbound_tx, outbound_rx) = outbound::channel();
+        *self.writer.write().await = Some(tokio::spawn(outbound::run_writer(ws_write, outbound_rx)));
+        *self.outbound.write().await = Some(outbound_tx);
         
         // Send initial registration
         self.register_agent().await?;
         
-        Ok(())
+        Ok(ws_read)
     }
 
     /// Register this agent with the server
     async fn register_agent(&self) -> Result<()> {
-        let system_info = self.get_system_info();
+        let system_info = Self::system_info();
+        let timestamp = chrono::Utc::now().timestamp();
+        let signature = match &self.config.credential {
+            Some(credential) => Some(credential.sign_registration(&self.config.agent_id, timestamp)?),
+            // A support code stands in for enrollment
+            None if self.config.support_code.is_some() => None,
+            None => {
+                warn!("Agent is not enrolled; the relay will reject it unless unauthenticated agents are allowed");
+                None
+            }
+        };
         
         let register_msg = RelayMessage::AgentRegister {
             agent_id: self.config.agent_id.clone(),
             hostname: self.config.hostname.clone(),
             os_info: system_info,
-            capabilities: vec![
-                "screen_capture".to_string(),
-                "input_control".to_string(),
-                "file_transfer".to_string(),
-                "multi_monitor".to_string(),
-                "monitor_selection".to_string(),
-                "high_fps_capture".to_string(),
-                "session_recording".to_string(),
-            ],
+            capabilities: self.capabilities.clone(),
+            timestamp,
+            signature,
+            binary_protocol: protocol::SUPPORTED_VERSIONS.to_vec(),
+            compression: compression::SUPPORTED.iter().map(|codec| codec.to_string()).collect(),
+            interfaces: network::interfaces(),
+            tailnet: network::tailnet().await,
+            support_code: self.config.support_code.clone(),
         };
         
         self.send_message(register_msg).await?;
@@ -201,102 +831,298 @@ impl RelayConnection {
     }
 
     /// Start background task to handle incoming messages
-    async fn start_message_handler(&self) -> Result<()> {
-        let ws_stream = Arc::clone(&self.ws_stream);
+    async fn start_message_handler(&self, mut ws_read: SplitStream<WsStream>) -> Result<()> {
+        let outbound = self.sender().await?;
         let heartbeat_manager = Arc::clone(&self.heartbeat_manager);
+        let message_tx = self.message_tx.clone();
+        let binary_version = Arc::clone(&self.binary_version);
+        let compression = Arc::clone(&self.compression);
+        let pongs = self.pongs.clone();
         
         tokio::spawn(async move {
-            loop {
-                let mut stream_guard = ws_stream.write().await;
-                
-                if let Some(ref mut stream) = *stream_guard {
-                    match stream.next().await {
-                        Some(Ok(Message::Text(text))) => {
-                            if let Err(e) = Self::handle_text_message(&text).await {
-                                error!("Error handling text message: {}", e);
-                            }
+            while let Some(result) = ws_read.next().await {
+                match result {
+                    Ok(Message::Text(text)) => {
+                        if let Err(e) = Self::handle_text_message(&text, &message_tx, &binary_version, &compression).await {
+                            error!("Error handling text message: {}", e);
                         }
-                        Some(Ok(Message::Binary(data))) => {
-                            if let Err(e) = Self::handle_binary_message(&data).await {
-                                error!("Error handling binary message: {}", e);
-                            }
+                    }
+                    Ok(Message::Binary(data)) => {
+                        if let Err(e) = Self::handle_binary_message(&data, &message_tx, &binary_version, &compression).await {
+                            error!("Error handling binary message: {}", e);
                         }
-                        Some(Ok(Message::Frame(_))) => {
-                            // Handle frame messages if needed
-                            debug!("Received frame message (not yet implemented)");
+                    }
+                    Ok(Message::Frame(_)) => {
+                        // Handle frame messages if needed
+                        debug!("Received frame message (not yet implemented)");
+                    }
+                    Ok(Message::Ping(data)) => {
+                        if let Err(e) = outbound.send(Message::Pong(data), MessagePriority::Critical).await {
+                            error!("Failed to send pong: {}", e);
                         }
-                        Some(Ok(Message::Ping(data))) => {
-                            if let Err(e) = stream.send(Message::Pong(data)).await {
-                                error!("Failed to send pong: {}", e);
+                    }
+                    Ok(Message::Pong(data)) => {
+                        let mut hb_guard = heartbeat_manager.write().await;
+                        hb_guard.record_success();
+                        let _ = pongs.send(data);
+                    }
+                    Ok(Message::Close(frame)) => {
+                        match frame {
+                            Some(frame) => {
+                                log_close_frame(u16::from(frame.code), &frame.reason);
+                                // Retrying with the same code can't succeed
+                                if u16::from(frame.code) == enrollment::CLOSE_INVALID_SUPPORT_CODE {
+                                    let closed = RelayMessage::SupportCodeClosed {
+                                        code: String::new(),
+                                        reason: frame.reason.to_string(),
+                                    };
+                                    let _ = message_tx.send(closed).await;
+                                }
                             }
+                            None => warn!("WebSocket connection closed by server"),
                         }
-                        Some(Ok(Message::Pong(_))) => {
-                            let mut hb_guard = heartbeat_manager.write().await;
-                            hb_guard.record_success();
-                        }
-                        Some(Ok(Message::Close(_))) => {
-                            warn!("WebSocket connection closed by server");
-                            break;
-                        }
-                        Some(Err(e)) => {
-                            error!("WebSocket error: {}", e);
-                            break;
-                        }
-                        None => {
-                            warn!("WebSocket stream ended");
-                            break;
-                        }
+                        break;
+                    }
+                    Err(e) => {
+                        error!("WebSocket error: {}", e);
+                        break;
                     }
-                } else {
-                    break;
                 }
             }
+            
+            warn!("WebSocket stream ended");
         });
         
         Ok(())
     }
 
     /// Handle incoming text messages
-    async fn handle_text_message(text: &str) -> Result<()> {
+    async fn handle_text_message(
+        text: &str,
+        message_tx: &mpsc::Sender<RelayMessage>,
+        binary_version: &AtomicU8,
+        compression: &AtomicBool,
+    ) -> Result<()> {
         debug!("Received text message: {}", text);
         
         let message: RelayMessage = serde_json::from_str(text)
             .context("Failed to parse relay message")?;
         
         match message {
-            RelayMessage::SessionRequest { session_id, session_type, requester } => {
+            RelayMessage::SessionRequest { ref session_id, ref session_type, ref requester, .. } => {
                 info!("Received session request: {} ({}) from {}", session_id, session_type, requester);
-                // TODO: Handle session request
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
             }
-            RelayMessage::SessionEnd { session_id } => {
+            RelayMessage::SessionEnd { ref session_id, .. } => {
                 info!("Session ended: {}", session_id);
-                // TODO: Clean up session
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
             }
-            RelayMessage::InputEvent { session_id, event_type, data } => {
-                debug!("Input event for session {}: {} {:?}", session_id, event_type, data);
-                
-                // Forward to input service if available
-                // TODO: Get input service from session manager and forward event
-                if let Ok(json_str) = serde_json::to_string(&data) {
-                    trace!("Input event JSON: {}", json_str);
-                    // The actual input processing will be handled by InputService
-                }
+            RelayMessage::SessionKeepalive { ref session_id } => {
+                debug!("Keepalive for session {}", session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::InputEvent { ref session_id, ref event_type, .. } => {
+                trace!("Input event for session {}: {}", session_id, event_type);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::CollectArchive { ref session_id, ref path, .. } => {
+                info!("Archive collection requested for session {}: {}", session_id, path);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::RegistryRequest { ref session_id, ref operation, .. } => {
+                info!("Registry {} requested for session {}: {}", operation.name(), session_id, operation.path());
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ElevationRequest { ref session_id, ref level } => {
+                info!("Elevation to {:?} approved for session {}", level, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SetEncoderProfile { ref session_id, profile } => {
+                info!("Encoder profile {} requested for session {}", profile, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SetQuality { ref session_id, quality } => {
+                debug!("Quality {} requested for session {}", quality, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SetBandwidthCap { ref session_id, kbps } => {
+                debug!("Bandwidth cap {:?} kbit/s requested for session {}", kbps, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::RequestKeyframe { ref session_id } => {
+                debug!("Keyframe requested for session {}", session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
             }
-            RelayMessage::MonitorControl { session_id, data } => {
+            RelayMessage::StreamDowngrade { ref session_id, ref reason } => {
+                warn!("Viewer of session {} cannot decode the stream: {}", session_id, reason.as_deref().unwrap_or("no reason given"));
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SetCursorStreaming { ref session_id, enabled } => {
+                debug!("Cursor streaming {} for session {}", if enabled { "enabled" } else { "disabled" }, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SetInputMode { ref session_id, mode } => {
+                debug!("Input mode {:?} requested for session {}", mode, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SetInputPreview { ref session_id, enabled } => {
+                info!("Input preview {} for session {}", if enabled { "requested" } else { "ended" }, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SessionOperatorChanged { ref session_id, ref requester } => {
+                info!("Session {} handed over to {}", session_id, requester);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ClipboardSync { ref session_id, ref content_type, .. } => {
+                debug!("Clipboard update ({}) for session {}", content_type, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::FileTransferStart { ref session_id, ref filename, total_size, .. } => {
+                info!("Incoming file {} ({} bytes) for session {}", filename, total_size, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::FileTransfer { ref transfer_id, chunk_index, .. } => {
+                trace!("File chunk {} of transfer {}", chunk_index, transfer_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::FileTransferResume { .. }
+            | RelayMessage::FileTransferComplete { .. }
+            | RelayMessage::FileTransferCancel { .. }
+            | RelayMessage::FileTransferRequest { .. } => {
+                debug!("File transfer control message: {:?}", message);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ListDirectory { .. }
+            | RelayMessage::StatEntry { .. }
+            | RelayMessage::CreateDirectory { .. }
+            | RelayMessage::Rename { .. }
+            | RelayMessage::Delete { .. } => {
+                debug!("File browser request: {:?}", message);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::MonitorControl { ref session_id, ref data } => {
                 debug!("Monitor control message for session {}: {:?}", session_id, data);
-                
-                // Parse monitor control message
-                if let Ok(monitor_msg) = serde_json::from_value::<crate::connection::monitor_protocol::MonitorControlMessage>(data) {
-                    info!("Processing monitor control: {:?}", monitor_msg.message_type());
-                    // TODO: Forward to monitor manager for processing
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::P2PHandshake { ref session_id, .. }
+            | RelayMessage::P2PResponse { ref session_id, .. } => {
+                debug!("P2P negotiation message for session {}", session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::UdpLaneOffer { ref session_id, port, .. } => {
+                debug!("UDP lane on port {} offered for session {}", port, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::UdpLaneClosed { ref session_id, .. } => {
+                info!("Relay closed the UDP lane of session {}", session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::LatencyProbe { ref session_id, sequence, .. } => {
+                trace!("Latency probe {} for session {}", sequence, session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::TerminalOpen { ref session_id, ref shell, .. } => {
+                info!("Terminal ({}) requested for session {}", shell.as_deref().unwrap_or("default shell"), session_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::TerminalInput { .. }
+            | RelayMessage::TerminalResize { .. }
+            | RelayMessage::TerminalClose { .. }
+            | RelayMessage::TerminalScrollbackRequest { .. } => {
+                trace!("Terminal message: {:?}", message);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ChatMessage { .. }
+            | RelayMessage::ChatReceipt { .. }
+            | RelayMessage::ChatError { .. } => {
+                trace!("Chat message: {:?}", message);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SupportCodeAccepted { ref code, expires_at } => {
+                info!("Support code {} accepted, valid until {}", code, expires_at);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::SupportCodeClosed { ref reason, .. } => {
+                info!("Support code closed: {}", reason);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ControlChanged { ref holder } => {
+                debug!("Input control moved to {:?}", holder);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::PolicyUpdate { .. } => {
+                info!("Received policy update");
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ExecuteTool { ref job } => {
+                info!("Deployment {} sent execution {}", job.deployment_id, job.execution_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ToolResultAccepted { execution_id } => {
+                debug!("Server accepted the result of execution {}", execution_id);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::WakeOnLan { ref mac, .. } => {
+                info!("Wake-on-LAN requested for {}", mac);
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            RelayMessage::ProtocolAccepted { version, compression: codec } => {
+                if protocol::SUPPORTED_VERSIONS.contains(&version) {
+                    info!("Relay accepted binary protocol v{}", version);
+                    binary_version.store(version, Ordering::SeqCst);
                 } else {
-                    warn!("Failed to parse monitor control message");
+                    warn!("Relay accepted binary protocol v{}, which this agent never offered", version);
+                }
+                // Relays from before compression answer without a codec
+                match codec.as_deref() {
+                    Some(codec) if compression::SUPPORTED.contains(&codec) => {
+                        info!("Relay accepted {} compression", codec);
+                        compression.store(true, Ordering::SeqCst);
+                    }
+                    Some(codec) => warn!("Relay picked {} compression, which this agent never offered", codec),
+                    None => debug!("Relay does not compress"),
                 }
             }
             RelayMessage::Ping => {
                 // Ping handled automatically by WebSocket protocol
             }
-            RelayMessage::Error { code, message } => {
+            RelayMessage::Error { code, message, .. } => {
                 error!("Server error {}: {}", code, message);
             }
             _ => {
@@ -307,12 +1133,32 @@ impl RelayConnection {
         Ok(())
     }
 
-    /// Handle incoming binary messages (frame data from server)
-    async fn handle_binary_message(data: &[u8]) -> Result<()> {
+    /// Handle incoming binary messages (envelopes or frame data from server)
+    async fn handle_binary_message(
+        data: &[u8],
+        message_tx: &mpsc::Sender<RelayMessage>,
+        binary_version: &AtomicU8,
+        compression: &AtomicBool,
+    ) -> Result<()> {
         use crate::capture::frame_protocol::FrameMessage;
         
         debug!("Received binary message: {} bytes", data.len());
         
+        if protocol::is_envelope(data) {
+            // Compressed JSON is handled as if it had come as text
+            let envelope = Envelope::decode(data).context("Malformed envelope from relay")?;
+            if envelope.kind == MessageKind::ControlText {
+                let payload = compression::payload(&envelope).context("Malformed compressed message")?;
+                let text = std::str::from_utf8(&payload).ok().context("Compressed message is not UTF-8")?;
+                return Self::handle_text_message(text, message_tx, binary_version, compression).await;
+            }
+            for message in Self::decode_envelope(data)? {
+                message_tx.send(message).await
+                    .map_err(agent_gone)?;
+            }
+            return Ok(());
+        }
+        
         // Try to parse as frame message
         match FrameMessage::deserialize_binary(data) {
             Ok(frame_msg) => {
@@ -333,49 +1179,215 @@ impl RelayConnection {
         Ok(())
     }
 
+    /// Turn an envelope from the relay into the messages the agent handles
+    pub(crate) fn decode_envelope(data: &[u8]) -> Result<Vec<RelayMessage>> {
+        let envelope = Envelope::decode(data).context("Malformed envelope from relay")?;
+        let session_id = envelope.session_id.to_string();
+        let payload = compression::payload(&envelope).context("Malformed compressed envelope")?;
+
+        match envelope.kind {
+            MessageKind::FileChunk => {
+                let chunk = FileChunkBody::decode(&payload).context("Malformed file chunk")?;
+                trace!("File chunk {} of transfer {}", chunk.chunk_index, chunk.transfer_id);
+                Ok(vec![RelayMessage::FileTransfer {
+                    session_id,
+                    transfer_id: chunk.transfer_id.to_string(),
+                    file_data: chunk.data.to_vec(),
+                    filename: chunk.filename.to_string(),
+                    total_size: chunk.total_size,
+                    chunk_index: chunk.chunk_index,
+                    total_chunks: chunk.total_chunks,
+                }])
+            }
+            MessageKind::InputBatch => {
+                let events = protocol::decode_input_batch(&payload).context("Malformed input batch")?;
+                trace!("Input batch of {} events for session {}", events.len(), session_id);
+                events
+                    .into_iter()
+                    .map(|event| {
+                        let data = serde_json::from_slice(event.data)
+                            .with_context(|| format!("Malformed {} input event", event.event_type))?;
+                        Ok(RelayMessage::InputEvent {
+                            session_id: session_id.clone(),
+                            event_type: event.event_type.to_string(),
+                            data,
+                        })
+                    })
+                    .collect()
+            }
+            MessageKind::ControlText => {
+                let message = serde_json::from_slice(&payload).context("Malformed compressed message")?;
+                Ok(vec![message])
+            }
+            MessageKind::ScreenFrame | MessageKind::TerminalData => {
+                debug!("Ignoring {:?} envelope for session {}", envelope.kind, session_id);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Envelope for bulk messages once the relay has accepted binary
+    fn encode_envelope(&self, message: &RelayMessage) -> Option<Vec<u8>> {
+        if self.binary_version.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        match message {
+            RelayMessage::ScreenFrame { session_id, frame_data, width, height, format } => {
+                let body = ScreenFrameBody { width: *width, height: *height, format, data: frame_data }.encode();
+                let compressed = self.compress_frame(session_id, &body);
+                let session_id = Uuid::parse_str(session_id).ok()?;
+                let envelope = match &compressed {
+                    Some(compressed) => Envelope::new(MessageKind::ScreenFrame, session_id, compressed)
+                        .with_flags(protocol::flags::COMPRESSED),
+                    None => Envelope::new(MessageKind::ScreenFrame, session_id, &body),
+                };
+                Some(envelope.encode())
+            }
+            RelayMessage::FileTransfer {
+                session_id,
+                transfer_id,
+                file_data,
+                filename,
+                total_size,
+                chunk_index,
+                total_chunks,
+            } => {
+                let body = FileChunkBody {
+                    transfer_id,
+                    filename,
+                    total_size: *total_size,
+                    chunk_index: *chunk_index,
+                    total_chunks: *total_chunks,
+                    data: file_data,
+                };
+                let last = chunk_index.saturating_add(1) >= *total_chunks;
+                let session_id = Uuid::parse_str(session_id).ok()?;
+                let envelope = Envelope::new(MessageKind::FileChunk, session_id, &body.encode())
+                    .with_flags(if last { protocol::flags::LAST } else { 0 })
+                    .encode();
+                Some(envelope)
+            }
+            RelayMessage::TerminalOutput { session_id, data } => {
+                let session_id = Uuid::parse_str(session_id).ok()?;
+                Some(Envelope::new(MessageKind::TerminalData, session_id, data).encode())
+            }
+            _ => None,
+        }
+    }
+
+    /// `json` as a compressed envelope, once the relay agreed to a codec
+    fn compress_text(&self, json: &str) -> Option<Vec<u8>> {
+        let policy = &self.config.compression;
+        if !self.compression.load(Ordering::SeqCst) || json.len() < policy.threshold_bytes {
+            return None;
+        }
+        let envelope = policy.compress_text(json);
+        self.text_compression.record(json.len(), envelope.as_ref().map_or(json.len(), Vec::len));
+        envelope
+    }
+
+    /// `body` of a frame compressed, if its session has frame compression
+    /// on right now and it saves anything
+    fn compress_frame(&self, session_id: &str, body: &[u8]) -> Option<Vec<u8>> {
+        if !self.compression.load(Ordering::SeqCst) {
+            return None;
+        }
+        let sessions = self.frame_compression.lock().unwrap_or_else(|e| e.into_inner());
+        let session = sessions.get(session_id).filter(|session| session.active)?;
+        let compressed = compression::compress(body, compression::FRAME_LEVEL, self.config.compression.max_ratio);
+        session.counters.record(body.len(), compressed.as_ref().map_or(body.len(), Vec::len));
+        compressed
+    }
+
+    /// Turn frame compression of a session that asked for it on or off
+    pub fn set_frame_compression(&self, session_id: &str, active: bool) {
+        let mut sessions = self.frame_compression.lock().unwrap_or_else(|e| e.into_inner());
+        let session = sessions.entry(session_id.to_string()).or_default();
+        if session.active != active {
+            debug!("Frame compression {} for session {}", if active { "on" } else { "off" }, session_id);
+            session.active = active;
+        }
+    }
+
+    /// Forget frame compression of sessions no longer in `sessions`
+    pub fn retain_frame_compression(&self, sessions: &[String]) {
+        let mut frame_compression = self.frame_compression.lock().unwrap_or_else(|e| e.into_inner());
+        frame_compression.retain(|session_id, _| sessions.contains(session_id));
+    }
+
+    /// Codec agreed with the relay and what it saved, for a session's
+    /// quality report
+    pub fn compression_report(&self, session_id: &str) -> CompressionReport {
+        let codec = self.compression.load(Ordering::SeqCst).then(|| compression::ZSTD.to_string());
+        let sessions = self.frame_compression.lock().unwrap_or_else(|e| e.into_inner());
+        let session = sessions.get(session_id);
+        CompressionReport {
+            codec,
+            text: self.text_compression.snapshot(),
+            frames: session.map(|session| session.counters.snapshot()).unwrap_or_default(),
+            frames_active: session.is_some_and(|session| session.active),
+        }
+    }
+
+    /// Sending half of the outbound queue for the current socket
+    async fn sender(&self) -> Result<outbound::OutboundSender> {
+        self.outbound.read().await
+            .clone()
+            .ok_or_else(|| ConnectionError::NotConnected.into())
+    }
+
     /// Send a message to the server
+    ///
+    /// Waits while the message's priority lane is full; see [`RelayMessage::priority`].
     pub async fn send_message(&self, message: RelayMessage) -> Result<()> {
-        let json = serde_json::to_string(&message)
-            .context("Failed to serialize message")?;
-        
-        let mut stream_guard = self.ws_stream.write().await;
+        // Frames and file chunks skip JSON once the relay speaks the envelope
+        let ws_message = match self.encode_envelope(&message) {
+            Some(envelope) => Message::Binary(envelope),
+            None => {
+                let json = serde_json::to_string(&message).context("Failed to serialize message")?;
+                match self.compress_text(&json) {
+                    Some(envelope) => Message::Binary(envelope),
+                    None => Message::Text(json),
+                }
+            }
+        };
         
-        if let Some(ref mut stream) = *stream_guard {
-            stream.send(Message::Text(json)).await
-                .context("Failed to send message")?;
-            debug!("Sent message: {:?}", message);
-        } else {
-            return Err(anyhow::anyhow!("WebSocket not connected"));
-        }
+        self.sender().await?
+            .send(ws_message, message.priority()).await?;
+        debug!("Queued message: {:?}", message);
         
         Ok(())
     }
     
     /// Send binary frame data directly (more efficient for video frames)
+    ///
+    /// Frames go in the video lane, which drops the oldest frame when the
+    /// uplink can't keep up.
     pub async fn send_binary_frame(&self, frame_data: Vec<u8>) -> Result<()> {
-        let mut stream_guard = self.ws_stream.write().await;
-        let frame_len = frame_data.len();
-
-        if let Some(ref mut stream) = *stream_guard {
-            stream.send(Message::Binary(frame_data)).await
-                .context("Failed to send binary frame")?;
-            trace!("Sent binary frame: {} bytes", frame_len);
-        } else {
-            return Err(anyhow::anyhow!("WebSocket not connected"));
-        }
+        self.send_binary(frame_data, MessagePriority::Normal).await
+    }
+
+    /// Send binary data in the given lane
+    pub async fn send_binary(&self, data: Vec<u8>, priority: MessagePriority) -> Result<()> {
+        let data_len = data.len();
+
+        self.sender().await?
+            .send(Message::Binary(data), priority).await?;
+        trace!("Queued {:?} binary message: {} bytes", priority, data_len);
 
         Ok(())
     }
 
+    /// Outbound queue metrics for the current socket
+    pub async fn queue_stats(&self) -> Option<OutboundStats> {
+        self.outbound.read().await.as_ref().map(|tx| tx.stats())
+    }
+
     /// Send heartbeat to server
-    pub async fn send_heartbeat(&self) -> Result<()> {
-        let heartbeat_data = HeartbeatMessage::new(
-            self.config.agent_id.clone(),
-            0, // TODO: Get actual active session count
-        );
-        
+    pub async fn send_heartbeat(&self, heartbeat: HeartbeatMessage) -> Result<()> {
         let heartbeat_msg = RelayMessage::Heartbeat {
-            data: heartbeat_data,
+            data: heartbeat,
         };
         
         match self.send_message(heartbeat_msg).await {
@@ -392,8 +1404,8 @@ impl RelayConnection {
         }
     }
 
-    /// Get system information for registration
-    fn get_system_info(&self) -> serde_json::Value {
+    /// System information sent in the registration, also printed by `info --output json`
+    pub fn system_info() -> serde_json::Value {
         use sysinfo::System;
         
         let mut sys = System::new_all();
@@ -411,6 +1423,8 @@ impl RelayConnection {
                 "available": sys.available_memory(),
             },
             "uptime": System::uptime(),
+            "platform": std::env::consts::OS,
+            "architecture": std::env::consts::ARCH,
             "agent_version": env!("CARGO_PKG_VERSION"),
         })
     }
@@ -419,21 +1433,145 @@ impl RelayConnection {
     pub async fn disconnect(&self) -> Result<()> {
         info!("Disconnecting from server");
         
-        let mut stream_guard = self.ws_stream.write().await;
+        // Queued last, so everything already waiting in any lane goes out
+        // first; the writer closes the socket once the close frame is delivered
+        if let Some(tx) = self.outbound.write().await.take() {
+            let close = CloseFrame {
+                code: CloseCode::Normal,
+                reason: "agent shutting down".into(),
+            };
+            let _ = tx.send(Message::Close(Some(close)), MessagePriority::Low).await;
+        }
         
-        if let Some(mut stream) = stream_guard.take() {
-            let _ = stream.close(None).await;
-            info!("WebSocket connection closed");
+        if let Some(writer) = self.writer.write().await.take() {
+            if tokio::time::timeout(CLOSE_TIMEOUT, writer).await.is_err() {
+                warn!("Relay writer did not drain within {:?}, dropping the socket", CLOSE_TIMEOUT);
+            }
         }
+        info!("WebSocket connection closed");
         
         Ok(())
     }
 
+    /// Time a WebSocket ping to the relay and back on this connection
+    pub async fn round_trip(&self) -> Result<Duration> {
+        let mut pongs = self.pongs.subscribe();
+        let payload = Uuid::new_v4().as_bytes().to_vec();
+        let started = std::time::Instant::now();
+        self.sender().await?
+            .send(Message::Ping(payload.clone()), MessagePriority::Critical).await?;
+        loop {
+            match pongs.recv().await {
+                Ok(data) if data == payload => return Ok(started.elapsed()),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Err(ConnectionError::NotConnected.into()),
+            }
+        }
+    }
+
     /// Check connection health
     pub async fn is_healthy(&self) -> bool {
-        let stream_guard = self.ws_stream.read().await;
+        let connected = self.outbound.read().await
+            .as_ref()
+            .is_some_and(|tx| !tx.is_closed());
         let hb_guard = self.heartbeat_manager.read().await;
         
-        stream_guard.is_some() && !hb_guard.is_connection_dead()
+        connected && !hb_guard.is_connection_dead()
+    }
+}
+
+/// Open a socket to the relay without registering, time one ping on it
+/// and close it again; `doctor` checks the relay this way while the
+/// installed agent keeps its own connection
+pub async fn probe_relay(config: &ClientConfig) -> Result<Duration> {
+    let url = server_url(config)?;
+    let (mut ws, _) = tls::connect(config, &url).await?;
+    let payload = Uuid::new_v4().as_bytes().to_vec();
+    let started = std::time::Instant::now();
+    ws.send(Message::Ping(payload.clone())).await.map_err(|e| closed(&url, e))?;
+    let rtt = loop {
+        match ws.next().await {
+            Some(Ok(Message::Pong(data))) if data == payload => break started.elapsed(),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(closed(&url, e)),
+            None => return Err(closed(&url, "relay closed the connection before answering")),
+        }
+    };
+    let _ = ws.close(None).await;
+    Ok(rtt)
+}
+
+/// The configured relay URL
+pub fn server_url(config: &ClientConfig) -> Result<Url> {
+    Url::parse(&config.server_url).map_err(|e| {
+        ConnectionError::InvalidServerUrl { url: config.server_url.clone(), reason: e.to_string() }.into()
+    })
+}
+
+fn closed(url: &Url, reason: impl std::fmt::Display) -> GhostLinkError {
+    ConnectionError::ConnectionFailed {
+        kind: ConnectionErrorKind::Closed,
+        url: url.to_string(),
+        reason: reason.to_string(),
+    }
+    .into()
+}
+
+/// The agent dropped its end of the message channel, i.e. is shutting down
+fn agent_gone<T>(_: mpsc::error::SendError<T>) -> GhostLinkError {
+    GhostLinkError::Other("Agent message channel closed".to_string())
+}
+
+/// Explain why the relay closed the connection
+fn log_close_frame(code: u16, reason: &str) {
+    match code {
+        enrollment::CLOSE_NOT_ENROLLED => error!(
+            "Relay rejected this agent: {}. Enroll it with `install --enroll-token <token>` or `start --enroll-token <token>`",
+            reason
+        ),
+        enrollment::CLOSE_INVALID_SIGNATURE => error!(
+            "Relay rejected this agent's signature: {}. Check the system clock, or re-enroll if the device key was replaced",
+            reason
+        ),
+        enrollment::CLOSE_BAD_REGISTRATION => error!("Relay rejected the registration: {}", reason),
+        enrollment::CLOSE_INVALID_SUPPORT_CODE => error!(
+            "Relay rejected the support code: {}. Ask the technician for a new one",
+            reason
+        ),
+        enrollment::CLOSE_REPLACED => warn!(
+            "Relay replaced this connection with a newer one for the same agent: {}. Check for a cloned device ID if this repeats",
+            reason
+        ),
+        enrollment::CLOSE_TOO_MANY_CONNECTIONS => warn!(
+            "Relay refused the connection: {}. Other agents behind this address hold all its connection slots; wait before reconnecting",
+            reason
+        ),
+        enrollment::CLOSE_SERVER_BUSY => warn!(
+            "Relay is at capacity: {}. Wait before reconnecting",
+            reason
+        ),
+        _ => warn!("WebSocket connection closed by server ({}): {}", code, reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_elevation_request_reaches_the_agent() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let text = r#"{"type":"ElevationRequest","session_id":"s1","level":"RunAsAdmin"}"#;
+        RelayConnection::handle_text_message(text, &tx, &AtomicU8::new(0), &AtomicBool::new(false))
+            .await
+            .unwrap();
+
+        match rx.try_recv().unwrap() {
+            RelayMessage::ElevationRequest { session_id, level } => {
+                assert_eq!(session_id, "s1");
+                assert_eq!(level, ElevationLevel::RunAsAdmin);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
     }
 }
@@ -1,4 +1,6 @@
+use crate::capture::displays::{CaptureTarget, DisplayEvent};
 use crate::capture::monitor_manager::{MonitorInfo, MonitorSelection, CaptureRegion, MonitorChangeEvent};
+use crate::capture::DisplayInfo;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -15,8 +17,15 @@ pub enum MonitorControlMessage {
         current_selection: MonitorSelection,
     },
     
+    /// Displays of the session's capture, sent on session start and hot-plug
+    MonitorList {
+        monitors: Vec<DisplayInfo>,
+        current: CaptureTarget,
+    },
+    
     /// Select a specific monitor
     SelectMonitor {
+        #[serde(alias = "display_id")]
         monitor_id: u32,
     },
     
@@ -268,6 +277,7 @@ impl MonitorProtocolHandler {
             
             // These are response/notification messages, don't handle directly
             MonitorControlMessage::MonitorsResponse { .. } |
+            MonitorControlMessage::MonitorList { .. } |
             MonitorControlMessage::SelectionResponse { .. } |
             MonitorControlMessage::MonitorChanged { .. } |
             MonitorControlMessage::ControlResponse { .. } => {
@@ -356,6 +366,19 @@ impl MonitorProtocolHandler {
 
 /// Monitor control message serialization helpers
 impl MonitorControlMessage {
+    /// Viewer notification for a display switch or hot-plug change
+    pub fn from_display_event(event: DisplayEvent) -> Self {
+        match event {
+            DisplayEvent::Switched { target, width, height, fallback } => MonitorControlMessage::MonitorChanged {
+                event: MonitorChangeEvent::CaptureSwitched { target, width, height, fallback },
+            },
+            DisplayEvent::ListChanged { displays, current } => MonitorControlMessage::MonitorList {
+                monitors: displays,
+                current,
+            },
+        }
+    }
+    
     /// Serialize message to JSON
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
@@ -371,6 +394,7 @@ impl MonitorControlMessage {
         match self {
             MonitorControlMessage::GetMonitors => "GetMonitors",
             MonitorControlMessage::MonitorsResponse { .. } => "MonitorsResponse",
+            MonitorControlMessage::MonitorList { .. } => "MonitorList",
             MonitorControlMessage::SelectMonitor { .. } => "SelectMonitor",
             MonitorControlMessage::CaptureAllMonitors { .. } => "CaptureAllMonitors",
             MonitorControlMessage::SetCaptureRegion { .. } => "SetCaptureRegion",
@@ -406,6 +430,7 @@ impl MonitorControlMessage {
     /// Check if message is a notification
     pub fn is_notification(&self) -> bool {
         matches!(self,
+            MonitorControlMessage::MonitorList { .. } |
             MonitorControlMessage::MonitorChanged { .. }
         )
     }
@@ -429,6 +454,12 @@ mod tests {
         }
     }
     
+    #[test]
+    fn test_select_monitor_accepts_display_id() {
+        let message = MonitorControlMessage::from_json(r#"{"type":"SelectMonitor","display_id":2}"#).unwrap();
+        assert!(matches!(message, MonitorControlMessage::SelectMonitor { monitor_id: 2 }));
+    }
+    
     #[test]
     fn test_message_type_classification() {
         let request = MonitorControlMessage::GetMonitors;
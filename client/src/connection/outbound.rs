@@ -0,0 +1,363 @@
+//! Prioritised outbound queue for the relay socket
+//!
+//! Mirrors the relay's per-connection queues: one bounded lane per priority,
+//! drained highest first by a single writer task. Only video frames drop
+//! their oldest entry when the lane is full; control, input and file data
+//! make the sender wait instead, so a slow uplink delays a transfer rather
+//! than corrupting it.
+
+use futures_util::{Sink, SinkExt};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, trace};
+
+/// Number of priority classes, indexed by `MessagePriority as usize`
+const CLASSES: usize = 4;
+
+/// Outbound message priority, highest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum MessagePriority {
+    /// Registration, heartbeats, pongs and errors
+    Critical = 0,
+    /// Session control and replies to the technician
+    High = 1,
+    /// Video frames; the oldest is dropped when the lane is full
+    Normal = 2,
+    /// Bulk data such as file chunks
+    Low = 3,
+}
+
+/// Lane capacities, indexed by `MessagePriority as usize`
+#[derive(Debug, Clone)]
+pub struct OutboundConfig {
+    pub capacities: [usize; CLASSES],
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            // Critical, High, Normal (video), Low
+            capacities: [64, 256, 8, 32],
+        }
+    }
+}
+
+/// The writer task has gone away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueClosed;
+
+impl std::fmt::Display for QueueClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "relay connection closed")
+    }
+}
+
+impl std::error::Error for QueueClosed {}
+
+/// Point-in-time queue metrics
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OutboundStats {
+    /// Messages waiting per class (critical, high, normal, low)
+    pub depth: [usize; CLASSES],
+    /// Payload bytes currently queued
+    pub queued_bytes: usize,
+    /// Frames dropped by the overflow policy per class
+    pub dropped: [u64; CLASSES],
+    /// Messages that had to wait for room
+    pub waited: u64,
+    /// Messages handed to the socket
+    pub sent: u64,
+}
+
+struct QueueState {
+    queues: [VecDeque<Message>; CLASSES],
+    stats: OutboundStats,
+    closed: bool,
+}
+
+struct Shared {
+    config: OutboundConfig,
+    senders: AtomicUsize,
+    state: Mutex<QueueState>,
+    /// Woken when a message is queued or the last sender goes away
+    ready: Notify,
+    /// Woken when the writer takes a message or the queue closes
+    space: Notify,
+}
+
+/// Sending half of the outbound queue
+pub struct OutboundSender {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of the outbound queue, owned by the writer task
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+impl Clone for OutboundSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for OutboundSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+/// Create an outbound queue with the default limits
+pub fn channel() -> (OutboundSender, OutboundReceiver) {
+    channel_with_config(OutboundConfig::default())
+}
+
+/// Create an outbound queue with custom limits
+pub fn channel_with_config(config: OutboundConfig) -> (OutboundSender, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        config,
+        senders: AtomicUsize::new(1),
+        state: Mutex::new(QueueState {
+            queues: Default::default(),
+            stats: OutboundStats::default(),
+            closed: false,
+        }),
+        ready: Notify::new(),
+        space: Notify::new(),
+    });
+
+    (OutboundSender { shared: shared.clone() }, OutboundReceiver { shared })
+}
+
+fn message_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(_) | Message::Frame(_) => 0,
+    }
+}
+
+fn is_droppable(priority: MessagePriority) -> bool {
+    priority == MessagePriority::Normal
+}
+
+impl OutboundSender {
+    /// Queue a message, waiting for room unless its lane drops old entries
+    pub async fn send(&self, mut message: Message, priority: MessagePriority) -> Result<(), QueueClosed> {
+        let mut waited = false;
+        loop {
+            // Registered before trying, so room made in between isn't missed
+            let space = self.shared.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            match self.try_send(message, priority) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed) => return Err(QueueClosed),
+                Err(TrySendError::Full(returned)) => {
+                    if !waited {
+                        waited = true;
+                        self.shared.state.lock().unwrap().stats.waited += 1;
+                    }
+                    message = returned;
+                    space.await;
+                }
+            }
+        }
+    }
+
+    /// Queue a message without waiting, handing it back if its lane is full
+    pub fn try_send(&self, message: Message, priority: MessagePriority) -> Result<(), TrySendError> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.closed {
+            return Err(TrySendError::Closed);
+        }
+
+        let class = priority as usize;
+        if state.queues[class].len() >= shared.config.capacities[class] {
+            if !is_droppable(priority) {
+                return Err(TrySendError::Full(message));
+            }
+            if let Some(oldest) = state.queues[class].pop_front() {
+                state.stats.queued_bytes -= message_len(&oldest);
+                state.stats.dropped[class] += 1;
+                trace!("Dropped oldest {:?} message from outbound queue", priority);
+            }
+        }
+
+        state.stats.queued_bytes += message_len(&message);
+        state.queues[class].push_back(message);
+        state.stats.depth[class] = state.queues[class].len();
+        drop(state);
+
+        shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Current queue metrics
+    pub fn stats(&self) -> OutboundStats {
+        self.shared.state.lock().unwrap().stats.clone()
+    }
+
+    /// Whether the writer has gone away
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+}
+
+/// Why [`OutboundSender::try_send`] could not queue a message
+#[derive(Debug)]
+pub enum TrySendError {
+    Closed,
+    Full(Message),
+}
+
+impl OutboundReceiver {
+    /// Take the next message, highest priority first.
+    ///
+    /// Returns `None` once every sender is gone and the queue is drained.
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.try_recv() {
+                return Some(message);
+            }
+            if self.shared.senders.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+
+    fn try_recv(&self) -> Option<Message> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+
+        let class = state.queues.iter().position(|queue| !queue.is_empty())?;
+        let message = state.queues[class].pop_front()?;
+        state.stats.depth[class] = state.queues[class].len();
+        state.stats.queued_bytes -= message_len(&message);
+        state.stats.sent += 1;
+        drop(state);
+
+        shared.space.notify_waiters();
+        Some(message)
+    }
+
+    /// Current queue metrics
+    pub fn stats(&self) -> OutboundStats {
+        self.shared.state.lock().unwrap().stats.clone()
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.queues.iter_mut().for_each(VecDeque::clear);
+        state.stats.depth = [0; CLASSES];
+        state.stats.queued_bytes = 0;
+        drop(state);
+
+        // Senders waiting for room learn the connection is gone
+        self.shared.space.notify_waiters();
+    }
+}
+
+/// Forward queued messages to the socket until it fails, a close frame is
+/// sent, or every sender is gone.
+pub async fn run_writer<S>(mut sink: S, rx: OutboundReceiver)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    while let Some(message) = rx.recv().await {
+        let closing = matches!(message, Message::Close(_));
+        if let Err(e) = sink.send(message).await {
+            debug!("Relay writer stopped: {}", e);
+            break;
+        }
+        if closing {
+            break;
+        }
+    }
+
+    let _ = sink.close().await;
+    debug!("Relay writer finished ({:?})", rx.stats());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn frame(i: u32) -> Message {
+        Message::Binary(i.to_be_bytes().to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_flood_keeps_priority_order() {
+        let (tx, rx) = channel();
+        for i in 0..1000 {
+            tx.send(frame(i), MessagePriority::Normal).await.unwrap();
+        }
+        tx.send(Message::Text("chunk".to_string()), MessagePriority::Low).await.unwrap();
+        tx.send(Message::Text("reply".to_string()), MessagePriority::High).await.unwrap();
+        tx.send(Message::Text("heartbeat".to_string()), MessagePriority::Critical).await.unwrap();
+        drop(tx);
+
+        let mut received = Vec::new();
+        while let Some(message) = rx.recv().await {
+            received.push(message);
+        }
+
+        let capacity = OutboundConfig::default().capacities[MessagePriority::Normal as usize];
+        let mut expected = vec![Message::Text("heartbeat".to_string()), Message::Text("reply".to_string())];
+        expected.extend((1000 - capacity as u32..1000).map(frame));
+        expected.push(Message::Text("chunk".to_string()));
+        assert_eq!(received, expected);
+
+        let stats = rx.stats();
+        assert_eq!(stats.dropped, [0, 0, 1000 - capacity as u64, 0]);
+        assert_eq!(stats.sent, expected.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_file_chunks_wait_instead_of_dropping() {
+        let (tx, rx) = channel_with_config(OutboundConfig { capacities: [1, 1, 1, 1] });
+        tx.send(Message::Binary(vec![1]), MessagePriority::Low).await.unwrap();
+        assert!(matches!(tx.try_send(Message::Binary(vec![2]), MessagePriority::Low), Err(TrySendError::Full(_))));
+
+        let waiting = {
+            let tx = tx.clone();
+            tokio::spawn(async move { tx.send(Message::Binary(vec![2]), MessagePriority::Low).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished(), "a full lane should hold the sender back");
+
+        assert_eq!(rx.recv().await, Some(Message::Binary(vec![1])));
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap().unwrap();
+        assert_eq!(rx.recv().await, Some(Message::Binary(vec![2])));
+        assert_eq!(tx.stats().dropped, [0; CLASSES]);
+        assert_eq!(tx.stats().waited, 1);
+
+        // Waiting senders are released when the writer goes away
+        tx.send(Message::Binary(vec![3]), MessagePriority::Low).await.unwrap();
+        let waiting = {
+            let tx = tx.clone();
+            tokio::spawn(async move { tx.send(Message::Binary(vec![4]), MessagePriority::Low).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(rx);
+        let result = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(result, Err(QueueClosed));
+        assert!(tx.is_closed());
+    }
+}
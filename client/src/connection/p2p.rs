@@ -1,12 +1,78 @@
-use anyhow::Result;
+//! Direct UDP connections between agent and viewer
+//!
+//! Each side binds one UDP socket, learns its public mapping and NAT type over
+//! STUN on that same socket, and sends the result to the peer in a
+//! `P2PHandshake`/`P2PResponse` through the relay. Both then punch towards
+//! each other's candidates until an authenticated probe gets through. Peers
+//! on the same tailnet first probe each other's tailnet address alone, and
+//! punch through their NATs only when that goes unanswered.
+//!
+//! The socket is dual-stack where the system allows, so candidates of both
+//! families are gathered and exchanged: a public mapping per family from
+//! STUN and the addresses of the machine's adapters. Probes alternate
+//! between the families and the first candidate to answer is used, so the
+//! family that punches through wins without either waiting on the other.
+//!
+//! The handshake also carries an ephemeral X25519 key. The channel derives one
+//! ChaCha20-Poly1305 key per direction from it, so datagrams are authenticated
+//! against the keys the relay delivered over TLS and a third party on the path
+//! can neither read nor inject traffic.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf;
+use ring::rand::SystemRandom;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
 use tokio::time::timeout;
-use tracing::{debug, info};
+use tracing::{debug, info, trace};
 use uuid::Uuid;
 
+use super::stun;
+use crate::network::{self, TailnetAddress};
+use crate::error::{Context, GhostLinkError, Result};
+
+/// How often probes are repeated while punching
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long peers on the same tailnet probe only its addresses
+const TAILNET_ATTEMPT: Duration = Duration::from_secs(2);
+
+/// Most further addresses offered to the peer
+const MAX_ADDRESSES: usize = 8;
+
+/// Documentation addresses the LAN address of each family is routed towards
+/// when no STUN server of that family is known; no packet is sent
+const ROUTE_PROBE_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 9);
+const ROUTE_PROBE_V6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), 9);
+
+/// Plaintext bytes per datagram, below common path MTUs
+const MAX_FRAGMENT: usize = 1150;
+
+/// Largest message the channel reassembles
+const MAX_FRAGMENTS: usize = 4096;
+
+/// Incomplete messages kept for reassembly; older ones are given up
+const MAX_PARTIAL_MESSAGES: usize = 32;
+
+/// How long a fragment waits for the rest of its message
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const PACKET_HEADER_LEN: usize = 9;
+const FRAGMENT_HEADER_LEN: usize = 8;
+
+const PACKET_PROBE: u8 = 1;
+const PACKET_PROBE_ACK: u8 = 2;
+const PACKET_DATA: u8 = 3;
+const PACKET_KEEPALIVE: u8 = 4;
+const PACKET_CLOSE: u8 = 5;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct P2PConnectionInfo {
     pub session_id: String,
@@ -14,328 +80,770 @@ pub struct P2PConnectionInfo {
     pub public_addr: Option<SocketAddr>,
     pub nat_type: NATType,
     pub connection_id: Uuid,
+    /// Ephemeral X25519 public key for the direct channel, base64
+    #[serde(default)]
+    pub public_key: String,
+    /// Tailnet address; the socket listens on it on `local_addr`'s port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tailnet: Option<TailnetAddress>,
+    /// Further candidates of either family: the public IPv6 address STUN
+    /// found, then the adapters' other addresses
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<SocketAddr>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NATType {
     Open,           // No NAT
     FullCone,       // Full cone NAT
-    RestrictedCone, // Restricted cone NAT  
+    RestrictedCone, // Restricted cone NAT
     PortRestricted, // Port restricted NAT
     Symmetric,      // Symmetric NAT
     Unknown,        // Could not determine
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PeerInfo {
-    pub peer_id: String,
-    pub local_addr: SocketAddr,
-    pub public_addr: Option<SocketAddr>,
-    pub nat_type: NATType,
-    pub relay_servers: Vec<SocketAddr>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ConnectionMethod {
-    Direct,         // Direct P2P connection
-    HolePunching,   // TCP hole punching
-    Relay,          // Through relay server
+impl NATType {
+    /// Whether hole punching can work between this NAT and the peer's.
+    ///
+    /// A symmetric NAT picks a new port per destination, so the peer can only
+    /// reach it if the peer accepts packets from any port.
+    pub fn can_punch_with(&self, peer: &NATType) -> bool {
+        !matches!(
+            (self, peer),
+            (NATType::Symmetric, NATType::Symmetric | NATType::PortRestricted)
+                | (NATType::PortRestricted, NATType::Symmetric)
+        )
+    }
 }
 
+/// One side of a direct connection attempt
 pub struct P2PManager {
     session_id: String,
+    socket: Arc<UdpSocket>,
     local_info: P2PConnectionInfo,
-    relay_servers: Vec<SocketAddr>,
-    rendezvous_server: SocketAddr,
+    private_key: EphemeralPrivateKey,
 }
 
 impl P2PManager {
-    pub async fn new(
-        session_id: String,
-        relay_servers: Vec<SocketAddr>,
-        rendezvous_server: SocketAddr,
-    ) -> Result<Self> {
+    /// Bind a UDP socket and discover its public address through the given
+    /// STUN servers
+    pub async fn new(session_id: String, stun_servers: &[SocketAddr]) -> Result<Self> {
         info!("Initializing P2P manager for session: {}", session_id);
-        
-        let local_info = Self::discover_local_info(&session_id).await?;
-        
+
+        let socket = network::dual_stack_udp(0).context("Failed to bind P2P socket")?;
+        let port = socket.local_addr()?.port();
+        let (ipv6_servers, ipv4_servers): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            stun_servers.iter().partition(|server| server.is_ipv6());
+        let route_v4 = Self::route_ip(ipv4_servers.first().copied().unwrap_or(ROUTE_PROBE_V4));
+        let route_v6 = Self::route_ip(ipv6_servers.first().copied().unwrap_or(ROUTE_PROBE_V6))
+            .filter(|_| socket.local_addr().is_ok_and(|local| local.is_ipv6()));
+        let local_addr = SocketAddr::new(route_v4.or(route_v6).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), port);
+        info!("Local address discovered: {}", local_addr);
+
+        // NAT type is that of IPv4 when it answers; IPv6 rarely has NAT
+        let (public_v4, nat_v4) = match route_v4 {
+            Some(ip) if !ipv4_servers.is_empty() => stun::discover(&socket, SocketAddr::new(ip, port), &ipv4_servers).await,
+            _ => (None, NATType::Unknown),
+        };
+        let (public_v6, nat_v6) = match route_v6 {
+            Some(ip) if !ipv6_servers.is_empty() => stun::discover(&socket, SocketAddr::new(ip, port), &ipv6_servers).await,
+            _ => (None, NATType::Unknown),
+        };
+        let (public_addr, nat_type) = match public_v4 {
+            Some(_) => (public_v4, nat_v4),
+            None => (public_v6, nat_v6),
+        };
+        let adapters = network::interfaces()
+            .into_iter()
+            .flat_map(|interface| interface.ipv4.into_iter().chain(interface.ipv6))
+            .filter_map(|cidr| cidr.split('/').next()?.parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, port));
+        let addresses: Vec<SocketAddr> = public_v6
+            .filter(|_| public_v4.is_some())
+            .into_iter()
+            .chain(adapters)
+            .filter(|addr| usable(addr) && Some(*addr) != public_addr && *addr != local_addr)
+            .filter(|addr| addr.is_ipv4() || route_v6.is_some())
+            .take(MAX_ADDRESSES)
+            .collect();
+        info!("Public address {:?}, NAT type {:?}, further candidates {:?}", public_addr, nat_type, addresses);
+
+        let rng = SystemRandom::new();
+        let private_key = EphemeralPrivateKey::generate(&X25519, &rng)
+            .map_err(|_| GhostLinkError::Other("Failed to generate P2P key".to_string()))?;
+        let public_key = private_key.compute_public_key()
+            .map_err(|_| GhostLinkError::Other("Failed to derive P2P public key".to_string()))?;
+
         Ok(Self {
+            local_info: P2PConnectionInfo {
+                session_id: session_id.clone(),
+                local_addr,
+                public_addr,
+                nat_type,
+                connection_id: Uuid::new_v4(),
+                public_key: BASE64.encode(public_key.as_ref()),
+                tailnet: None,
+                addresses,
+            },
             session_id,
-            local_info,
-            relay_servers,
-            rendezvous_server,
+            socket: Arc::new(socket),
+            private_key,
         })
     }
-    
-    async fn discover_local_info(session_id: &str) -> Result<P2PConnectionInfo> {
-        // Bind to a random port to discover local address
-        let listener = TcpListener::bind("0.0.0.0:0").await?;
-        let local_addr = listener.local_addr()?;
-        
-        info!("Local address discovered: {}", local_addr);
-        
-        // TODO: Implement STUN-like discovery for public address and NAT type
-        let public_addr = Self::discover_public_address().await;
-        let nat_type = Self::detect_nat_type(&local_addr, &public_addr).await;
-        
-        Ok(P2PConnectionInfo {
-            session_id: session_id.to_string(),
-            local_addr,
-            public_addr,
-            nat_type,
-            connection_id: Uuid::new_v4(),
-        })
+
+    /// Address of the interface that routes towards `target`, used as the
+    /// LAN candidate of its family; `None` without such a route. No packet
+    /// is sent.
+    fn route_ip(target: SocketAddr) -> Option<IpAddr> {
+        let unspecified = match target {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        std::net::UdpSocket::bind((unspecified, 0))
+            .and_then(|socket| {
+                socket.connect(target)?;
+                socket.local_addr()
+            })
+            .map(|addr| addr.ip())
+            .ok()
+    }
+
+    /// Offer this machine's tailnet address to the peer
+    pub fn with_tailnet(mut self, tailnet: Option<TailnetAddress>) -> Self {
+        self.local_info.tailnet = tailnet;
+        self
     }
-    
-    async fn discover_public_address() -> Option<SocketAddr> {
-        // TODO: Implement STUN server query to discover public IP/port
-        // For now, return None (behind NAT)
-        debug!("Public address discovery not yet implemented");
-        None
-    }
-    
-    async fn detect_nat_type(
-        _local_addr: &SocketAddr,
-        public_addr: &Option<SocketAddr>,
-    ) -> NATType {
-        match public_addr {
-            Some(_) => {
-                // TODO: Implement proper NAT type detection using STUN
-                debug!("NAT type detection not yet implemented");
-                NATType::Unknown
+
+    pub fn get_local_info(&self) -> &P2PConnectionInfo {
+        &self.local_info
+    }
+
+    pub fn is_p2p_capable(&self) -> bool {
+        !matches!(self.local_info.nat_type, NATType::Symmetric)
+    }
+
+    /// Whether punching towards this peer is worth attempting; NATs don't
+    /// matter between peers on the same tailnet, nor between peers that
+    /// both have a global IPv6 address
+    pub fn can_reach(&self, peer: &P2PConnectionInfo) -> bool {
+        tailnet_candidate(&self.local_info, peer).is_some()
+            || (has_global_ipv6(&self.local_info) && has_global_ipv6(peer))
+            || self.local_info.nat_type.can_punch_with(&peer.nat_type)
+    }
+
+    /// Punch through to the peer and return an encrypted channel, giving up
+    /// after `deadline`
+    pub async fn connect(self, peer: &P2PConnectionInfo, deadline: Duration) -> Result<DirectChannel> {
+        let local = self.socket.local_addr()?;
+        let tailnet = tailnet_candidate(&self.local_info, peer).filter(|address| network::socket_target(local, *address).is_some());
+        let ipv6 = local.is_ipv6() && has_ipv6(&self.local_info);
+        let candidates: Vec<SocketAddr> = tailnet.into_iter().chain(candidates(peer, ipv6)).collect();
+        if candidates.is_empty() {
+            return Err(GhostLinkError::Other("Peer has no usable address".to_string()));
+        }
+
+        let peer_key = BASE64.decode(&peer.public_key).context("Invalid peer key")?;
+        let crypto = ChannelCrypto::derive(self.private_key, &self.local_info.public_key, &peer_key)?;
+        let channel = DirectChannel::new(self.socket, crypto);
+        let started = Instant::now();
+
+        // The peer does the same, so neither is lured onto a NATed path
+        // while the tailnet would do
+        if let Some(address) = tailnet {
+            info!("Probing tailnet address {} for session {}", address, self.session_id);
+            if let Ok(punched) = timeout(TAILNET_ATTEMPT.min(deadline), channel.punch(&[address])).await {
+                punched?;
+                info!("Direct channel to {} established over the tailnet", channel.remote_addr());
+                return Ok(channel);
             }
-            None => NATType::Unknown,
+            debug!("Tailnet address {} did not answer", address);
         }
+
+        info!("Punching towards {:?} for session {}", candidates, self.session_id);
+        let remaining = deadline.saturating_sub(started.elapsed());
+        timeout(remaining, channel.punch(&candidates)).await
+            .map_err(|_| GhostLinkError::Other(format!("Hole punching timed out after {:?}", deadline)))??;
+
+        info!("Direct channel to {} established", channel.remote_addr());
+        Ok(channel)
     }
-    
-    pub async fn connect_to_peer(&self, peer_info: PeerInfo) -> Result<TcpStream> {
-        info!("Attempting to connect to peer: {}", peer_info.peer_id);
-        
-        // Try connection methods in order of preference
-        let methods = self.get_connection_methods(&peer_info);
-        
-        for method in methods {
-            match self.try_connection_method(&peer_info, &method).await {
-                Ok(stream) => {
-                    info!("Connected to peer using method: {:?}", method);
-                    return Ok(stream);
-                }
-                Err(e) => {
-                    debug!("Connection method {:?} failed: {}", method, e);
-                    continue;
-                }
-            }
+}
+
+/// The peer's tailnet address, when both ends are on the same tailnet
+fn tailnet_candidate(local: &P2PConnectionInfo, peer: &P2PConnectionInfo) -> Option<SocketAddr> {
+    let (ours, theirs) = (local.tailnet.as_ref()?, peer.tailnet.as_ref()?);
+    (ours.tailnet == theirs.tailnet).then(|| SocketAddr::new(theirs.address, peer.local_addr.port()))
+}
+
+/// Addresses to punch towards, public mapping first; IPv6 ones only when
+/// this side has IPv6 too
+fn candidates(peer: &P2PConnectionInfo, ipv6: bool) -> Vec<SocketAddr> {
+    let all = [peer.public_addr, Some(peer.local_addr)].into_iter().flatten().chain(peer.addresses.iter().copied());
+    order_candidates(all, ipv6)
+}
+
+/// Usable, distinct candidates alternating between IPv6 and IPv4, each
+/// family in the order given, so a round of probes reaches the best
+/// candidate of both families first
+fn order_candidates(addresses: impl IntoIterator<Item = SocketAddr>, ipv6: bool) -> Vec<SocketAddr> {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for addr in addresses {
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let family = if addr.is_ipv4() { &mut v4 } else { &mut v6 };
+        if usable(&addr) && !family.contains(&addr) && (addr.is_ipv4() || ipv6) {
+            family.push(addr);
         }
-        
-        Err(anyhow::anyhow!("All connection methods failed"))
-    }
-    
-    fn get_connection_methods(&self, peer_info: &PeerInfo) -> Vec<ConnectionMethod> {
-        let mut methods = Vec::new();
-        
-        // Try direct connection first if both have public addresses
-        if self.local_info.public_addr.is_some() && peer_info.public_addr.is_some() {
-            methods.push(ConnectionMethod::Direct);
+    }
+
+    let mut ordered = Vec::with_capacity(v4.len() + v6.len());
+    let (mut v4, mut v6) = (v4.into_iter(), v6.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Whether a peer may be reached at `addr`; link-local IPv6 addresses
+/// would need the peer's interface scope
+fn usable(addr: &SocketAddr) -> bool {
+    let ip = addr.ip();
+    let link_local_v6 = matches!(ip, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80);
+    !ip.is_unspecified() && !ip.is_multicast() && !link_local_v6 && addr.port() != 0
+}
+
+fn has_ipv6(info: &P2PConnectionInfo) -> bool {
+    [info.public_addr, Some(info.local_addr)].into_iter().flatten().chain(info.addresses.iter().copied()).any(|addr| addr.is_ipv6())
+}
+
+/// Whether `info` offers a global IPv6 address, reachable from anywhere
+/// barring a firewall, which punching opens like a NAT
+fn has_global_ipv6(info: &P2PConnectionInfo) -> bool {
+    [info.public_addr, Some(info.local_addr)].into_iter().flatten().chain(info.addresses.iter().copied()).any(|addr| {
+        // 2000::/3 is all that is handed out globally
+        matches!(addr.ip(), IpAddr::V6(v6) if v6.segments()[0] & 0xe000 == 0x2000) && usable(&addr)
+    })
+}
+
+/// Per-direction keys, nonce counter and replay window
+struct ChannelCrypto {
+    send_key: LessSafeKey,
+    recv_key: LessSafeKey,
+    send_counter: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+}
+
+impl ChannelCrypto {
+    fn derive(private_key: EphemeralPrivateKey, our_public: &str, peer_public: &[u8]) -> Result<Self> {
+        let our_public = BASE64.decode(our_public).context("Invalid local key")?;
+        let peer_key = UnparsedPublicKey::new(&X25519, peer_public);
+
+        // Key order decides direction, so both sides agree without a role
+        let (low, high) = if our_public.as_slice() < peer_public {
+            (our_public.as_slice(), peer_public)
+        } else {
+            (peer_public, our_public.as_slice())
+        };
+        let we_are_low = low == our_public.as_slice();
+
+        let (low_to_high, high_to_low) = agreement::agree_ephemeral(private_key, &peer_key, |shared| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"ghostlink-p2p-v1").extract(shared);
+            let key = |label: &'static [u8]| -> Result<LessSafeKey> {
+                let info = [label, low, high];
+                let okm = prk
+                    .expand(&info, &CHACHA20_POLY1305)
+                    .map_err(|_| GhostLinkError::Other("Key derivation failed".to_string()))?;
+                Ok(LessSafeKey::new(UnboundKey::from(okm)))
+            };
+            Ok::<_, GhostLinkError>((key(b"low-to-high")?, key(b"high-to-low")?))
+        })
+        .map_err(|_| GhostLinkError::Other("Key agreement with peer failed".to_string()))??;
+
+        let (send_key, recv_key) = if we_are_low { (low_to_high, high_to_low) } else { (high_to_low, low_to_high) };
+        Ok(Self {
+            send_key,
+            recv_key,
+            send_counter: AtomicU64::new(0),
+            replay: Mutex::new(ReplayWindow::default()),
+        })
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn seal(&self, kind: u8, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let counter = self.send_counter.fetch_add(1, Ordering::SeqCst);
+        let mut packet = Vec::with_capacity(PACKET_HEADER_LEN + plaintext.len() + CHACHA20_POLY1305.tag_len());
+        packet.push(kind);
+        packet.extend_from_slice(&counter.to_be_bytes());
+
+        let mut body = plaintext.to_vec();
+        self.send_key
+            .seal_in_place_append_tag(Self::nonce(counter), Aad::from(&packet[..PACKET_HEADER_LEN]), &mut body)
+            .map_err(|_| GhostLinkError::Other("Encryption failed".to_string()))?;
+        packet.extend_from_slice(&body);
+        Ok(packet)
+    }
+
+    /// Authenticate and decrypt a datagram, rejecting replays
+    fn open(&self, packet: &[u8]) -> Option<(u8, Vec<u8>)> {
+        if packet.len() < PACKET_HEADER_LEN + CHACHA20_POLY1305.tag_len() {
+            return None;
         }
-        
-        // Try hole punching for NAT traversal
-        if !matches!(self.local_info.nat_type, NATType::Symmetric) 
-            && !matches!(peer_info.nat_type, NATType::Symmetric) {
-            methods.push(ConnectionMethod::HolePunching);
+        let (header, ciphertext) = packet.split_at(PACKET_HEADER_LEN);
+        let counter = u64::from_be_bytes(header[1..].try_into().ok()?);
+        if !self.replay.lock().unwrap().check(counter) {
+            return None;
         }
-        
-        // Always fall back to relay
-        methods.push(ConnectionMethod::Relay);
-        
-        methods
-    }
-    
-    async fn try_connection_method(
-        &self,
-        peer_info: &PeerInfo,
-        method: &ConnectionMethod,
-    ) -> Result<TcpStream> {
-        match method {
-            ConnectionMethod::Direct => {
-                self.try_direct_connection(peer_info).await
+
+        let mut body = ciphertext.to_vec();
+        let plaintext_len = self.recv_key
+            .open_in_place(Self::nonce(counter), Aad::from(header), &mut body)
+            .ok()?
+            .len();
+        body.truncate(plaintext_len);
+
+        self.replay.lock().unwrap().accept(counter);
+        Some((header[0], body))
+    }
+}
+
+/// Sliding window of recently seen packet counters
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit n set: `highest - n` was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let age = highest - counter;
+                age < 64 && self.seen & (1 << age) == 0
             }
-            ConnectionMethod::HolePunching => {
-                self.try_hole_punching(peer_info).await
+        }
+    }
+
+    fn accept(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.seen |= 1 << (highest - counter),
+            Some(highest) => {
+                let shift = counter - highest;
+                self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+                self.seen |= 1;
+                self.highest = Some(counter);
             }
-            ConnectionMethod::Relay => {
-                self.try_relay_connection(peer_info).await
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
             }
         }
     }
-    
-    async fn try_direct_connection(&self, peer_info: &PeerInfo) -> Result<TcpStream> {
-        if let Some(public_addr) = peer_info.public_addr {
-            debug!("Attempting direct connection to: {}", public_addr);
-            
-            let stream = timeout(
-                Duration::from_secs(5),
-                TcpStream::connect(public_addr)
-            ).await??;
-            
-            info!("Direct connection established");
-            return Ok(stream);
+}
+
+/// Fragments of a message still being received
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: Instant,
+}
+
+struct ChannelState {
+    remote: Option<SocketAddr>,
+    last_seen: Instant,
+    partial: HashMap<u32, PartialMessage>,
+}
+
+/// Encrypted, message-oriented UDP channel to a punched peer.
+///
+/// Messages larger than a datagram are fragmented; a message with a lost
+/// fragment is dropped, which suits frames and input batches that are
+/// superseded by the next one anyway.
+pub struct DirectChannel {
+    socket: Arc<UdpSocket>,
+    crypto: ChannelCrypto,
+    next_message_id: AtomicU32,
+    state: Mutex<ChannelState>,
+}
+
+impl DirectChannel {
+    fn new(socket: Arc<UdpSocket>, crypto: ChannelCrypto) -> Self {
+        Self {
+            socket,
+            crypto,
+            next_message_id: AtomicU32::new(0),
+            state: Mutex::new(ChannelState {
+                remote: None,
+                last_seen: Instant::now(),
+                partial: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Probe all candidates until the peer answers
+    async fn punch(&self, candidates: &[SocketAddr]) -> Result<()> {
+        let local = self.socket.local_addr()?;
+        let targets: Vec<SocketAddr> = candidates.iter().filter_map(|candidate| network::socket_target(local, *candidate)).collect();
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let probe = self.crypto.seal(PACKET_PROBE, &[])?;
+            for candidate in &targets {
+                // Unreachable candidates are expected while punching
+                if let Err(e) = self.socket.send_to(&probe, candidate).await {
+                    trace!("Probe to {} failed: {}", candidate, e);
+                }
+            }
+
+            let wait = async {
+                loop {
+                    let (len, from) = self.socket.recv_from(&mut buf).await?;
+                    if let Some((kind, _)) = self.crypto.open(&buf[..len]) {
+                        return Ok::<_, std::io::Error>((kind, from));
+                    }
+                }
+            };
+            let Ok(result) = timeout(PROBE_INTERVAL, wait).await else { continue };
+            let (kind, from) = result.context("P2P socket failed while punching")?;
+
+            self.set_remote(from);
+            if kind == PACKET_PROBE {
+                self.send_packet(PACKET_PROBE_ACK, &[]).await?;
+            }
+            debug!("Peer answered from {}", from);
+            return Ok(());
         }
-        
-        Err(anyhow::anyhow!("No public address available for direct connection"))
-    }
-    
-    async fn try_hole_punching(&self, peer_info: &PeerInfo) -> Result<TcpStream> {
-        info!("Attempting TCP hole punching");
-        
-        // Step 1: Register with rendezvous server
-        self.register_with_rendezvous().await?;
-        
-        // Step 2: Coordinate hole punching through rendezvous server
-        let punch_info = self.coordinate_hole_punch(&peer_info.peer_id).await?;
-        
-        // Step 3: Simultaneous connection attempts
-        let stream = self.perform_hole_punch(&punch_info).await?;
-        
-        info!("Hole punching successful");
-        Ok(stream)
-    }
-    
-    async fn register_with_rendezvous(&self) -> Result<()> {
-        debug!("Registering with rendezvous server: {}", self.rendezvous_server);
-        
-        // TODO: Implement rendezvous server registration
-        // Send our local_info to the rendezvous server
-        
-        Ok(())
     }
-    
-    async fn coordinate_hole_punch(&self, peer_id: &str) -> Result<HolePunchInfo> {
-        debug!("Coordinating hole punch with peer: {}", peer_id);
-        
-        // TODO: Implement rendezvous server coordination
-        // Exchange connection info and timing with peer
-        
-        Ok(HolePunchInfo {
-            target_addr: "127.0.0.1:12345".parse()?,
-            start_time: std::time::Instant::now() + Duration::from_secs(1),
-        })
+
+    fn set_remote(&self, remote: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.remote = Some(remote);
+        state.last_seen = Instant::now();
+    }
+
+    /// Address the peer's packets arrive from
+    pub fn remote_addr(&self) -> SocketAddr {
+        let remote = self.remote();
+        SocketAddr::new(remote.ip().to_canonical(), remote.port())
+    }
+
+    /// The peer's address as the socket sends to it, v4-mapped on IPv6 sockets
+    fn remote(&self) -> SocketAddr {
+        self.state.lock().unwrap().remote.expect("channel is punched")
+    }
+
+    /// Time since the peer was last heard from
+    pub fn idle_for(&self) -> Duration {
+        self.state.lock().unwrap().last_seen.elapsed()
+    }
+
+    async fn send_packet(&self, kind: u8, plaintext: &[u8]) -> Result<()> {
+        let packet = self.crypto.seal(kind, plaintext)?;
+        self.socket.send_to(&packet, self.remote()).await
+            .context("Failed to send on direct channel")?;
+        Ok(())
     }
-    
-    async fn perform_hole_punch(&self, punch_info: &HolePunchInfo) -> Result<TcpStream> {
-        debug!("Performing hole punch to: {}", punch_info.target_addr);
-        
-        // Wait for coordinated start time
-        let now = std::time::Instant::now();
-        if punch_info.start_time > now {
-            tokio::time::sleep(punch_info.start_time - now).await;
+
+    /// Send one message, fragmenting it as needed
+    pub async fn send(&self, data: &[u8]) -> Result<()> {
+        let count = data.len().div_ceil(MAX_FRAGMENT).max(1);
+        if count > MAX_FRAGMENTS {
+            return Err(GhostLinkError::Other(format!("Message of {} bytes is too large for the direct channel", data.len())));
         }
-        
-        // Simultaneous connection attempts
-        let mut attempts = 0;
-        while attempts < 10 {
-            match TcpStream::connect(punch_info.target_addr).await {
-                Ok(stream) => return Ok(stream),
-                Err(_) => {
-                    attempts += 1;
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                }
-            }
+        let message_id = self.next_message_id.fetch_add(1, Ordering::SeqCst);
+
+        for index in 0..count {
+            let fragment = &data[(index * MAX_FRAGMENT).min(data.len())..((index + 1) * MAX_FRAGMENT).min(data.len())];
+            let mut plaintext = Vec::with_capacity(FRAGMENT_HEADER_LEN + fragment.len());
+            plaintext.extend_from_slice(&message_id.to_be_bytes());
+            plaintext.extend_from_slice(&(index as u16).to_be_bytes());
+            plaintext.extend_from_slice(&(count as u16).to_be_bytes());
+            plaintext.extend_from_slice(fragment);
+            self.send_packet(PACKET_DATA, &plaintext).await?;
         }
-        
-        Err(anyhow::anyhow!("Hole punching attempts exhausted"))
-    }
-    
-    async fn try_relay_connection(&self, peer_info: &PeerInfo) -> Result<TcpStream> {
-        info!("Attempting relay connection");
-        
-        for relay_addr in &self.relay_servers {
-            match self.connect_through_relay(relay_addr, &peer_info.peer_id).await {
-                Ok(stream) => {
-                    info!("Relay connection established through: {}", relay_addr);
-                    return Ok(stream);
-                }
-                Err(e) => {
-                    debug!("Relay {} failed: {}", relay_addr, e);
-                    continue;
+        Ok(())
+    }
+
+    /// Tell the peer we are still here, keeping NAT mappings open
+    pub async fn keepalive(&self) -> Result<()> {
+        self.send_packet(PACKET_KEEPALIVE, &[]).await
+    }
+
+    /// Tell the peer the channel is going away
+    pub async fn close(&self) {
+        let _ = self.send_packet(PACKET_CLOSE, &[]).await;
+    }
+
+    /// Receive the next complete message.
+    ///
+    /// Late probes are answered and keepalives absorbed along the way. Fails
+    /// once the peer closes the channel. Only one task should receive.
+    pub async fn recv(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await
+                .context("Direct channel socket failed")?;
+            let Some((kind, plaintext)) = self.crypto.open(&buf[..len]) else {
+                continue;
+            };
+            // The peer's mapping may change mid-session; follow it
+            self.set_remote(from);
+
+            match kind {
+                PACKET_PROBE => self.send_packet(PACKET_PROBE_ACK, &[]).await?,
+                PACKET_DATA => {
+                    if let Some(message) = self.reassemble(&plaintext) {
+                        return Ok(message);
+                    }
                 }
+                PACKET_CLOSE => return Err(GhostLinkError::Other("Peer closed the direct channel".to_string())),
+                _ => {}
             }
         }
-        
-        Err(anyhow::anyhow!("All relay servers failed"))
-    }
-    
-    async fn connect_through_relay(
-        &self,
-        relay_addr: &SocketAddr,
-        _peer_id: &str,
-    ) -> Result<TcpStream> {
-        debug!("Connecting through relay: {}", relay_addr);
-        
-        let stream = TcpStream::connect(relay_addr).await?;
-        
-        // TODO: Implement relay protocol
-        // Send relay request with peer_id
-        // Handle relay response
-        
-        Ok(stream)
-    }
-    
-    pub async fn wait_for_peer_connection(&self) -> Result<TcpStream> {
-        info!("Waiting for incoming peer connection");
-        
-        let listener = TcpListener::bind(self.local_info.local_addr).await?;
-        
-        // Register ourselves as available for connections
-        self.register_with_rendezvous().await?;
-        
-        // Wait for incoming connection
-        let (stream, addr) = listener.accept().await?;
-        info!("Incoming connection from: {}", addr);
-        
-        // TODO: Verify connection is from expected peer
-        
-        Ok(stream)
-    }
-    
-    pub fn get_local_info(&self) -> &P2PConnectionInfo {
-        &self.local_info
     }
-    
-    pub fn is_p2p_capable(&self) -> bool {
-        !matches!(self.local_info.nat_type, NATType::Symmetric)
-    }
-}
 
-#[derive(Debug)]
-struct HolePunchInfo {
-    target_addr: SocketAddr,
-    start_time: std::time::Instant,
+    fn reassemble(&self, plaintext: &[u8]) -> Option<Vec<u8>> {
+        if plaintext.len() < FRAGMENT_HEADER_LEN {
+            return None;
+        }
+        let message_id = u32::from_be_bytes(plaintext[0..4].try_into().ok()?);
+        let index = u16::from_be_bytes(plaintext[4..6].try_into().ok()?) as usize;
+        let count = u16::from_be_bytes(plaintext[6..8].try_into().ok()?) as usize;
+        let fragment = &plaintext[FRAGMENT_HEADER_LEN..];
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            return None;
+        }
+        if count == 1 {
+            return Some(fragment.to_vec());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.partial.retain(|_, partial| partial.started.elapsed() < REASSEMBLY_TIMEOUT);
+        if !state.partial.contains_key(&message_id) && state.partial.len() >= MAX_PARTIAL_MESSAGES {
+            let oldest = state.partial.iter().min_by_key(|(_, partial)| partial.started).map(|(id, _)| *id)?;
+            state.partial.remove(&oldest);
+        }
+
+        let partial = state.partial.entry(message_id).or_insert_with(|| PartialMessage {
+            fragments: vec![None; count],
+            received: 0,
+            started: Instant::now(),
+        });
+        if partial.fragments.len() != count || partial.fragments[index].is_some() {
+            return None;
+        }
+        partial.fragments[index] = Some(fragment.to_vec());
+        partial.received += 1;
+        if partial.received < count {
+            return None;
+        }
+
+        let partial = state.partial.remove(&message_id)?;
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+    use crate::connection::stun::tests::spawn_stun_server;
+
+    async fn manager(session: &str, stun: SocketAddr) -> P2PManager {
+        P2PManager::new(session.to_string(), &[stun]).await.unwrap()
+    }
+
     #[tokio::test]
     async fn test_p2p_manager_creation() {
-        let relay_servers = vec!["127.0.0.1:8080".parse().unwrap()];
-        let rendezvous_server = "127.0.0.1:8081".parse().unwrap();
-        
-        let manager = P2PManager::new(
-            "test_session".to_string(),
-            relay_servers,
-            rendezvous_server,
-        ).await;
-        
-        assert!(manager.is_ok());
-    }
-    
+        let manager = P2PManager::new("test_session".to_string(), &[]).await.unwrap();
+        let info = manager.get_local_info();
+        assert_eq!(info.nat_type, NATType::Unknown);
+        assert!(info.public_addr.is_none());
+        assert_eq!(BASE64.decode(&info.public_key).unwrap().len(), 32);
+    }
+
     #[test]
     fn test_nat_type_serialization() {
         let nat_type = NATType::FullCone;
         let serialized = serde_json::to_string(&nat_type).unwrap();
         let deserialized: NATType = serde_json::from_str(&serialized).unwrap();
-        
+
         assert!(matches!(deserialized, NATType::FullCone));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_punchable_nat_pairs() {
+        assert!(NATType::FullCone.can_punch_with(&NATType::Symmetric));
+        assert!(NATType::RestrictedCone.can_punch_with(&NATType::Symmetric));
+        assert!(NATType::PortRestricted.can_punch_with(&NATType::PortRestricted));
+        assert!(NATType::Unknown.can_punch_with(&NATType::Unknown));
+        assert!(!NATType::Symmetric.can_punch_with(&NATType::Symmetric));
+        assert!(!NATType::Symmetric.can_punch_with(&NATType::PortRestricted));
+        assert!(!NATType::PortRestricted.can_punch_with(&NATType::Symmetric));
+    }
+
+    #[tokio::test]
+    async fn test_same_tailnet_skips_nat_checks() {
+        let on = |address: &str| Some(TailnetAddress { tailnet: "tail1234.ts.net".to_string(), address: address.parse().unwrap() });
+        let mut local = P2PManager::new("session".to_string(), &[]).await.unwrap().with_tailnet(on("100.64.0.1"));
+        local.local_info.nat_type = NATType::Symmetric;
+        let mut peer = local.get_local_info().clone();
+        peer.nat_type = NATType::Symmetric;
+        peer.local_addr = "192.168.1.20:40123".parse().unwrap();
+        peer.tailnet = on("100.64.0.7");
+        assert_eq!(tailnet_candidate(local.get_local_info(), &peer), Some("100.64.0.7:40123".parse().unwrap()));
+        assert!(local.can_reach(&peer));
+
+        peer.tailnet.as_mut().unwrap().tailnet = "tail9999.ts.net".to_string();
+        assert_eq!(tailnet_candidate(local.get_local_info(), &peer), None);
+        peer.tailnet = None;
+        assert_eq!(tailnet_candidate(local.get_local_info(), &peer), None);
+    }
+
+    fn info(local_addr: &str, public_addr: Option<&str>, addresses: &[&str]) -> P2PConnectionInfo {
+        P2PConnectionInfo {
+            session_id: "session".to_string(),
+            local_addr: local_addr.parse().unwrap(),
+            public_addr: public_addr.map(|addr| addr.parse().unwrap()),
+            nat_type: NATType::Symmetric,
+            connection_id: Uuid::new_v4(),
+            public_key: String::new(),
+            tailnet: None,
+            addresses: addresses.iter().map(|addr| addr.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_candidate_order_with_mixed_families() {
+        let peer = info("192.168.1.20:40123", Some("203.0.113.9:40123"), &[
+            "[2001:db8::20]:40123",
+            "10.0.0.20:40123",
+            "[fd00::20]:40123",
+            // Unusable or already offered
+            "[fe80::20]:40123",
+            "[::ffff:203.0.113.9]:40123",
+            "0.0.0.0:40123",
+        ]);
+        let addrs = |list: &[&str]| list.iter().map(|addr| addr.parse().unwrap()).collect::<Vec<SocketAddr>>();
+        assert_eq!(candidates(&peer, true), addrs(&[
+            "[2001:db8::20]:40123",
+            "203.0.113.9:40123",
+            "[fd00::20]:40123",
+            "192.168.1.20:40123",
+            "10.0.0.20:40123",
+        ]));
+        assert_eq!(candidates(&peer, false), addrs(&["203.0.113.9:40123", "192.168.1.20:40123", "10.0.0.20:40123"]));
+
+        let ipv6_only = info("[2001:db8::30]:40000", Some("[2001:db8::30]:40000"), &[]);
+        assert_eq!(candidates(&ipv6_only, true), addrs(&["[2001:db8::30]:40000"]));
+        assert!(candidates(&ipv6_only, false).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_global_ipv6_skips_nat_checks() {
+        let mut local = P2PManager::new("session".to_string(), &[]).await.unwrap();
+        local.local_info = info("192.168.1.20:40000", Some("203.0.113.9:40000"), &["[2001:db8::20]:40000"]);
+        let peer = info("10.0.0.30:40000", Some("198.51.100.30:40000"), &["[2001:db8:1::30]:40000"]);
+        assert!(local.can_reach(&peer));
+
+        // Unique local addresses don't reach across sites
+        let peer = info("10.0.0.30:40000", Some("198.51.100.30:40000"), &["[fd00::30]:40000"]);
+        assert!(!local.can_reach(&peer));
+    }
+
+    #[tokio::test]
+    async fn test_punch_over_the_family_that_answers() {
+        let mut agent = manager("session", spawn_stun_server().await).await;
+        let mut viewer = manager("session", spawn_stun_server().await).await;
+        if !agent.socket.local_addr().unwrap().is_ipv6() || std::net::UdpSocket::bind("[::1]:0").is_err() {
+            // No IPv6 on this machine
+            return;
+        }
+        // Each offers a dead IPv4 address and the IPv6 loopback
+        for p2p in [&mut agent, &mut viewer] {
+            let port = p2p.socket.local_addr().unwrap().port();
+            p2p.local_info.public_addr = Some(SocketAddr::from(([192, 0, 2, 1], port)));
+            p2p.local_info.local_addr = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+            p2p.local_info.addresses.clear();
+        }
+        let agent_info = agent.get_local_info().clone();
+        let viewer_info = viewer.get_local_info().clone();
+
+        let (agent, viewer) = tokio::join!(
+            agent.connect(&viewer_info, Duration::from_secs(5)),
+            viewer.connect(&agent_info, Duration::from_secs(5)),
+        );
+        let (agent, viewer) = (agent.unwrap(), viewer.unwrap());
+        assert_eq!(agent.remote_addr(), viewer_info.local_addr);
+        assert_eq!(viewer.remote_addr(), agent_info.local_addr);
+
+        agent.send(b"over IPv6").await.unwrap();
+        assert_eq!(viewer.recv().await.unwrap(), b"over IPv6");
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        for counter in [5, 3, 7, 6, 100] {
+            assert!(window.check(counter));
+            window.accept(counter);
+            assert!(!window.check(counter));
+        }
+        assert!(window.check(99));
+        assert!(!window.check(7), "too old for the window");
+    }
+
+    #[tokio::test]
+    async fn test_punch_and_exchange_messages() {
+        let stun = spawn_stun_server().await;
+        let agent = manager("session", stun).await;
+        let viewer = manager("session", stun).await;
+        let agent_info = agent.get_local_info().clone();
+        let viewer_info = viewer.get_local_info().clone();
+        assert_eq!(agent_info.nat_type, NATType::Open);
+
+        let (agent, viewer) = tokio::join!(
+            agent.connect(&viewer_info, Duration::from_secs(5)),
+            viewer.connect(&agent_info, Duration::from_secs(5)),
+        );
+        let (agent, viewer) = (agent.unwrap(), viewer.unwrap());
+
+        // Larger than one datagram, so it is fragmented
+        let frame: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        agent.send(&frame).await.unwrap();
+        agent.send(b"second").await.unwrap();
+        assert_eq!(viewer.recv().await.unwrap(), frame);
+        assert_eq!(viewer.recv().await.unwrap(), b"second");
+
+        viewer.send(b"input").await.unwrap();
+        assert_eq!(agent.recv().await.unwrap(), b"input");
+
+        viewer.close().await;
+        assert!(agent.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_key_never_punches() {
+        let stun = spawn_stun_server().await;
+        let agent = manager("session", stun).await;
+        let viewer = manager("session", stun).await;
+        let impostor = manager("session", stun).await;
+        let mut viewer_info = viewer.get_local_info().clone();
+        // Right address, someone else's key
+        viewer_info.public_key = impostor.get_local_info().public_key.clone();
+        let agent_info = agent.get_local_info().clone();
+
+        let (agent, _) = tokio::join!(
+            agent.connect(&viewer_info, Duration::from_millis(500)),
+            viewer.connect(&agent_info, Duration::from_millis(500)),
+        );
+        assert!(agent.is_err());
+    }
+}
@@ -0,0 +1,559 @@
+//! Binary envelope for high-volume relay traffic
+//!
+//! Screen frames, file chunks, input batches and terminal output travel
+//! between agent and relay as WebSocket binary messages wrapped in this
+//! envelope, instead of JSON that spells every payload byte out as a number.
+//! Low-rate control messages stay JSON.
+//!
+//! The agent lists the envelope versions it speaks in `AgentRegister`
+//! (`binary_protocol`) and the relay answers with `ProtocolAccepted`. Either
+//! side that never sees the other's offer keeps to JSON, so old agents and
+//! old relays interoperate with new ones. Payloads may be compressed once
+//! both sides agree on a codec; see `compression`.
+//!
+//! This file is kept identical in the client and the server.
+//!
+//! Layout, integers little-endian:
+//!
+//! ```text
+//! magic "GLRP" (4) | version (1) | kind (1) | flags (2) | session id (16) | payload length (4) | payload
+//! ```
+
+use uuid::Uuid;
+
+/// First bytes of every envelope
+pub const MAGIC: &[u8; 4] = b"GLRP";
+
+/// Envelope version written by this build
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Envelope versions this build can read, offered during registration
+pub const SUPPORTED_VERSIONS: &[u8] = &[PROTOCOL_VERSION];
+
+/// Size of the fixed envelope header
+pub const HEADER_LEN: usize = 28;
+
+/// Largest payload accepted, well above any frame or file chunk
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024 * 1024;
+
+/// Envelope flag bits
+pub mod flags {
+    /// Screen frame that decodes without earlier frames
+    pub const KEYFRAME: u16 = 1 << 0;
+    /// Final chunk of a file transfer
+    pub const LAST: u16 = 1 << 1;
+    /// Payload compressed with the codec negotiated at registration
+    pub const COMPRESSED: u16 = 1 << 2;
+}
+
+/// What an envelope carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    ScreenFrame = 1,
+    FileChunk = 2,
+    InputBatch = 3,
+    /// Raw terminal output; the payload is the bytes the terminal printed
+    TerminalData = 4,
+    /// A JSON message that would otherwise go as text, sent as an envelope
+    /// so it can be compressed; the session id is nil
+    ControlText = 5,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            1 => Ok(MessageKind::ScreenFrame),
+            2 => Ok(MessageKind::FileChunk),
+            3 => Ok(MessageKind::InputBatch),
+            4 => Ok(MessageKind::TerminalData),
+            5 => Ok(MessageKind::ControlText),
+            other => Err(DecodeError::UnknownKind(other)),
+        }
+    }
+}
+
+/// Why a binary message could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message ends before a field it declares
+    Truncated { needed: usize, available: usize },
+    /// Not an envelope
+    BadMagic,
+    /// Written by a newer protocol version
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    /// The payload length disagrees with the message size
+    LengthMismatch { declared: usize, actual: usize },
+    /// The payload exceeds [`MAX_PAYLOAD_LEN`]
+    TooLarge(usize),
+    /// A text field is not valid UTF-8
+    InvalidText(&'static str),
+    /// A compressed field does not inflate
+    Corrupt(&'static str),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated { needed, available } => {
+                write!(f, "truncated message: need {} bytes, have {}", needed, available)
+            }
+            DecodeError::BadMagic => write!(f, "not a relay envelope"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported envelope version {}", version),
+            DecodeError::UnknownKind(kind) => write!(f, "unknown envelope kind {}", kind),
+            DecodeError::LengthMismatch { declared, actual } => {
+                write!(f, "payload length {} does not match the {} bytes received", declared, actual)
+            }
+            DecodeError::TooLarge(len) => write!(f, "payload of {} bytes exceeds the limit", len),
+            DecodeError::InvalidText(field) => write!(f, "{} is not valid UTF-8", field),
+            DecodeError::Corrupt(field) => write!(f, "{} is corrupt", field),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// One binary relay message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    pub kind: MessageKind,
+    pub flags: u16,
+    pub session_id: Uuid,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(kind: MessageKind, session_id: Uuid, payload: &'a [u8]) -> Self {
+        Self { kind, flags: 0, session_id, payload }
+    }
+
+    pub fn with_flags(mut self, flags: u16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Serialize header and payload into one WebSocket message
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.extend_from_slice(MAGIC);
+        out.push(PROTOCOL_VERSION);
+        out.push(self.kind as u8);
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(self.session_id.as_bytes());
+        out.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.payload);
+        out
+    }
+
+    /// Parse a WebSocket message, borrowing the payload
+    pub fn decode(data: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(data);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let kind = MessageKind::try_from(reader.u8()?)?;
+        let flags = reader.u16()?;
+        let session_id = Uuid::from_slice(reader.take(16)?).expect("16 bytes make a UUID");
+        let declared = reader.u32()? as usize;
+        if declared > MAX_PAYLOAD_LEN {
+            return Err(DecodeError::TooLarge(declared));
+        }
+        let payload = reader.rest();
+        if payload.len() != declared {
+            return Err(DecodeError::LengthMismatch { declared, actual: payload.len() });
+        }
+
+        Ok(Self { kind, flags, session_id, payload })
+    }
+}
+
+/// Whether a binary message claims to be an envelope
+pub fn is_envelope(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Highest envelope version both sides speak, if any
+pub fn negotiate(offered: &[u8]) -> Option<u8> {
+    offered.iter().copied().filter(|version| SUPPORTED_VERSIONS.contains(version)).max()
+}
+
+/// Payload of a [`MessageKind::ScreenFrame`] envelope
+///
+/// Layout: width (4) | height (4) | format length (1) | format | frame data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScreenFrameBody<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub format: &'a str,
+    pub data: &'a [u8],
+}
+
+impl<'a> ScreenFrameBody<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let format = truncate_str(self.format, u8::MAX as usize);
+        let mut out = Vec::with_capacity(9 + format.len() + self.data.len());
+        out.extend_from_slice(&self.width.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.push(format.len() as u8);
+        out.extend_from_slice(format.as_bytes());
+        out.extend_from_slice(self.data);
+        out
+    }
+
+    pub fn decode(payload: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(payload);
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let format_len = reader.u8()? as usize;
+        let format = reader.str(format_len, "frame format")?;
+        Ok(Self { width, height, format, data: reader.rest() })
+    }
+}
+
+/// Payload of a [`MessageKind::FileChunk`] envelope
+///
+/// Layout: total size (8) | chunk index (4) | total chunks (4) |
+/// transfer id length (1) | transfer id | filename length (2) | filename | data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChunkBody<'a> {
+    pub transfer_id: &'a str,
+    pub filename: &'a str,
+    pub total_size: u64,
+    pub chunk_index: u32,
+    pub total_chunks: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> FileChunkBody<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let transfer_id = truncate_str(self.transfer_id, u8::MAX as usize);
+        let filename = truncate_str(self.filename, u16::MAX as usize);
+        let mut out = Vec::with_capacity(19 + transfer_id.len() + filename.len() + self.data.len());
+        out.extend_from_slice(&self.total_size.to_le_bytes());
+        out.extend_from_slice(&self.chunk_index.to_le_bytes());
+        out.extend_from_slice(&self.total_chunks.to_le_bytes());
+        out.push(transfer_id.len() as u8);
+        out.extend_from_slice(transfer_id.as_bytes());
+        out.extend_from_slice(&(filename.len() as u16).to_le_bytes());
+        out.extend_from_slice(filename.as_bytes());
+        out.extend_from_slice(self.data);
+        out
+    }
+
+    pub fn decode(payload: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(payload);
+        let total_size = reader.u64()?;
+        let chunk_index = reader.u32()?;
+        let total_chunks = reader.u32()?;
+        let transfer_id_len = reader.u8()? as usize;
+        let transfer_id = reader.str(transfer_id_len, "transfer id")?;
+        let filename_len = reader.u16()? as usize;
+        let filename = reader.str(filename_len, "filename")?;
+        Ok(Self { transfer_id, filename, total_size, chunk_index, total_chunks, data: reader.rest() })
+    }
+}
+
+/// One event in a [`MessageKind::InputBatch`] envelope
+///
+/// `data` is the event's JSON body, as in a text `InputEvent` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputEventBody<'a> {
+    pub event_type: &'a str,
+    pub data: &'a [u8],
+}
+
+/// Pack input events into a batch payload
+///
+/// Layout: count (2), then per event: type length (1) | type | data length (4) | data
+pub fn encode_input_batch(events: &[InputEventBody<'_>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(events.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    for event in events.iter().take(u16::MAX as usize) {
+        let event_type = truncate_str(event.event_type, u8::MAX as usize);
+        out.push(event_type.len() as u8);
+        out.extend_from_slice(event_type.as_bytes());
+        out.extend_from_slice(&(event.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(event.data);
+    }
+    out
+}
+
+/// Unpack a batch payload
+pub fn decode_input_batch(payload: &[u8]) -> Result<Vec<InputEventBody<'_>>, DecodeError> {
+    let mut reader = Reader::new(payload);
+    let count = reader.u16()? as usize;
+    let mut events = Vec::with_capacity(count.min(payload.len() / 5));
+    for _ in 0..count {
+        let type_len = reader.u8()? as usize;
+        let event_type = reader.str(type_len, "input event type")?;
+        let data_len = reader.u32()? as usize;
+        let data = reader.take(data_len)?;
+        events.push(InputEventBody { event_type, data });
+    }
+    let trailing = reader.rest().len();
+    if trailing != 0 {
+        return Err(DecodeError::LengthMismatch { declared: payload.len() - trailing, actual: payload.len() });
+    }
+    Ok(events)
+}
+
+/// Longest prefix of `s` within `max` bytes that ends on a char boundary
+fn truncate_str(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Bounds-checked cursor over a message
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len()).ok_or(
+            DecodeError::Truncated { needed: self.pos.saturating_add(len), available: self.data.len() },
+        )?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.take(N)?.try_into().expect("take returns N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn str(&mut self, len: usize, field: &'static str) -> Result<&'a str, DecodeError> {
+        std::str::from_utf8(self.take(len)?).map_err(|_| DecodeError::InvalidText(field))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_chunk() -> Vec<u8> {
+        let body = FileChunkBody {
+            transfer_id: "7d7c1f1e",
+            filename: "report é.pdf",
+            total_size: 1_000_000,
+            chunk_index: 3,
+            total_chunks: 16,
+            data: &[0xAB; 300],
+        };
+        Envelope::new(MessageKind::FileChunk, Uuid::new_v4(), &body.encode())
+            .with_flags(flags::LAST)
+            .encode()
+    }
+
+    /// Deterministic xorshift so mutation tests are reproducible
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let session_id = Uuid::new_v4();
+        let payload = b"frame bytes";
+        let encoded = Envelope::new(MessageKind::ScreenFrame, session_id, payload)
+            .with_flags(flags::KEYFRAME)
+            .encode();
+        assert_eq!(encoded.len(), HEADER_LEN + payload.len());
+
+        let decoded = Envelope::decode(&encoded).unwrap();
+        assert_eq!(decoded.kind, MessageKind::ScreenFrame);
+        assert_eq!(decoded.session_id, session_id);
+        assert_eq!(decoded.payload, payload);
+        assert!(decoded.has_flag(flags::KEYFRAME));
+        assert!(!decoded.has_flag(flags::LAST));
+    }
+
+    #[test]
+    fn test_bodies_round_trip() {
+        let frame = ScreenFrameBody { width: 1920, height: 1080, format: "h264", data: &[1, 2, 3] };
+        assert_eq!(ScreenFrameBody::decode(&frame.encode()).unwrap(), frame);
+
+        let encoded = sample_chunk();
+        let envelope = Envelope::decode(&encoded).unwrap();
+        let chunk = FileChunkBody::decode(envelope.payload).unwrap();
+        assert_eq!(chunk.filename, "report é.pdf");
+        assert_eq!((chunk.chunk_index, chunk.total_chunks, chunk.total_size), (3, 16, 1_000_000));
+        assert_eq!(chunk.data, &[0xAB; 300][..]);
+
+        let events = [
+            InputEventBody { event_type: "mouse_move", data: br#"{"x":10,"y":20}"# },
+            InputEventBody { event_type: "key_down", data: br#"{"key":"a"}"# },
+        ];
+        assert_eq!(decode_input_batch(&encode_input_batch(&events)).unwrap(), events);
+        assert!(decode_input_batch(&encode_input_batch(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_every_truncation_is_rejected() {
+        let encoded = sample_chunk();
+        for len in 0..encoded.len() {
+            let result = Envelope::decode(&encoded[..len]);
+            assert!(result.is_err(), "prefix of {} bytes decoded", len);
+        }
+
+        let envelope = Envelope::decode(&encoded).unwrap();
+        // Cutting into the chunk header fails; cutting the data only shortens it
+        for len in 0..19 + "7d7c1f1e".len() + "report é.pdf".len() {
+            assert!(FileChunkBody::decode(&envelope.payload[..len]).is_err(), "prefix of {} bytes decoded", len);
+        }
+
+        let batch = encode_input_batch(&[InputEventBody { event_type: "key_down", data: b"{}" }]);
+        for len in 1..batch.len() {
+            assert!(decode_input_batch(&batch[..len]).is_err(), "prefix of {} bytes decoded", len);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_headers_are_rejected() {
+        let encoded = sample_chunk();
+
+        let mut bad = encoded.clone();
+        bad[0] = b'X';
+        assert_eq!(Envelope::decode(&bad), Err(DecodeError::BadMagic));
+
+        let mut bad = encoded.clone();
+        bad[4] = PROTOCOL_VERSION + 1;
+        assert_eq!(Envelope::decode(&bad), Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+
+        let mut bad = encoded.clone();
+        bad[5] = 0xEE;
+        assert_eq!(Envelope::decode(&bad), Err(DecodeError::UnknownKind(0xEE)));
+
+        let mut bad = encoded.clone();
+        bad.push(0);
+        assert!(matches!(Envelope::decode(&bad), Err(DecodeError::LengthMismatch { .. })));
+
+        let mut bad = encoded.clone();
+        bad[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Envelope::decode(&bad), Err(DecodeError::TooLarge(u32::MAX as usize)));
+
+        // Declared string lengths past the end of the payload
+        let mut body = FileChunkBody::decode(Envelope::decode(&encoded).unwrap().payload).unwrap().encode();
+        body[16] = u8::MAX;
+        body.truncate(40);
+        assert!(matches!(FileChunkBody::decode(&body), Err(DecodeError::Truncated { .. })));
+
+        let mut batch = encode_input_batch(&[InputEventBody { event_type: "key", data: b"{}" }]);
+        batch[0] = 0xFF;
+        assert!(matches!(decode_input_batch(&batch), Err(DecodeError::Truncated { .. })));
+    }
+
+    #[test]
+    fn test_random_mutations_never_panic() {
+        let encoded = sample_chunk();
+        let batch = encode_input_batch(&[
+            InputEventBody { event_type: "mouse_move", data: br#"{"x":1}"# },
+            InputEventBody { event_type: "key_up", data: br#"{"key":"b"}"# },
+        ]);
+        let mut state = 0x9E37_79B9_7F4A_7C15;
+
+        for _ in 0..20_000 {
+            let mut message = encoded.clone();
+            let mut body = batch.clone();
+            for _ in 0..1 + next(&mut state) % 4 {
+                let pos = next(&mut state) as usize % message.len();
+                message[pos] = next(&mut state) as u8;
+                let pos = next(&mut state) as usize % body.len();
+                body[pos] = next(&mut state) as u8;
+            }
+            message.truncate(next(&mut state) as usize % (message.len() + 1));
+
+            if let Ok(envelope) = Envelope::decode(&message) {
+                let _ = FileChunkBody::decode(envelope.payload);
+                let _ = ScreenFrameBody::decode(envelope.payload);
+                let _ = decode_input_batch(envelope.payload);
+            }
+            let _ = decode_input_batch(&body);
+        }
+    }
+
+    #[test]
+    fn test_negotiation_picks_shared_version() {
+        assert_eq!(negotiate(&[PROTOCOL_VERSION]), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(&[PROTOCOL_VERSION, 200]), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(&[200]), None);
+        assert_eq!(negotiate(&[]), None);
+    }
+
+    /// Wire size of a 64 KiB frame as JSON versus in the envelope
+    #[test]
+    fn bench_wire_bytes_for_sample_frame() {
+        let mut state = 0x2545_F491_4F6C_DD1D;
+        let frame: Vec<u8> = (0..64 * 1024).map(|_| next(&mut state) as u8).collect();
+        let session_id = Uuid::new_v4();
+
+        let json = serde_json::json!({
+            "type": "ScreenFrame",
+            "session_id": session_id.to_string(),
+            "frame_data": frame,
+            "width": 1920,
+            "height": 1080,
+            "format": "h264",
+        })
+        .to_string();
+
+        let body = ScreenFrameBody { width: 1920, height: 1080, format: "h264", data: &frame };
+        let binary = Envelope::new(MessageKind::ScreenFrame, session_id, &body.encode()).encode();
+
+        println!(
+            "64 KiB frame: {} bytes as JSON, {} bytes enveloped ({:.1}% of JSON, {} bytes of overhead)",
+            json.len(),
+            binary.len(),
+            binary.len() as f64 * 100.0 / json.len() as f64,
+            binary.len() - frame.len(),
+        );
+        assert!(binary.len() - frame.len() < 64);
+        assert!(json.len() > binary.len() * 3);
+    }
+}
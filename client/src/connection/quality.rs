@@ -0,0 +1,300 @@
+//! Link quality measurement
+//!
+//! The agent and the relay each send `LatencyProbe` messages and echo the
+//! other side's. A [`ProbeTracker`] turns the echoes into round-trip time,
+//! jitter (the RFC 3550 running mean deviation) and loss over the recent
+//! probes. The agent adds its capture pipeline's view and sends the result to
+//! the relay as a [`QualityReport`].
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::capture::adaptive::CaptureStats;
+use crate::capture::encoder_factory::SkippedEncoder;
+use crate::input::InputStats;
+use crate::session::watchdog::Component;
+use super::compression::CompressionReport;
+use super::outbound::OutboundStats;
+
+/// Unanswered probes count as lost after this long
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probe outcomes loss is computed over
+const LOSS_WINDOW: usize = 30;
+
+/// Weight of the newest sample in the smoothed round-trip time (RFC 6298)
+const RTT_WEIGHT: f64 = 0.125;
+
+/// Gain of the jitter estimate (RFC 3550)
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Round-trip time, jitter and loss of one link
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkQuality {
+    /// Smoothed round-trip time
+    pub rtt_ms: f64,
+    /// Mean deviation between successive round trips
+    pub jitter_ms: f64,
+    /// Share of recent probes that went unanswered
+    pub loss_percent: f64,
+    /// Probes answered or given up on so far
+    pub samples: u64,
+}
+
+/// Tracks outstanding probes and the measurements their echoes produce
+#[derive(Debug, Default)]
+pub struct ProbeTracker {
+    next_sequence: u32,
+    outstanding: HashMap<u32, Instant>,
+    /// Recent outcomes, true if answered
+    outcomes: VecDeque<bool>,
+    smoothed_rtt: Option<f64>,
+    last_rtt: Option<f64>,
+    jitter: f64,
+    samples: u64,
+}
+
+impl ProbeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a probe sent at `now` and return its sequence number
+    pub fn next_probe(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.outstanding.insert(sequence, now);
+        sequence
+    }
+
+    /// Record the echo of probe `sequence`, returning its round-trip time.
+    ///
+    /// Echoes of unknown or already expired probes are ignored.
+    pub fn record_echo(&mut self, sequence: u32, now: Instant) -> Option<Duration> {
+        let sent = self.outstanding.remove(&sequence)?;
+        let rtt = now.saturating_duration_since(sent);
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+
+        if let Some(last) = self.last_rtt {
+            self.jitter += JITTER_GAIN * ((rtt_ms - last).abs() - self.jitter);
+        }
+        self.last_rtt = Some(rtt_ms);
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed) => smoothed + RTT_WEIGHT * (rtt_ms - smoothed),
+            None => rtt_ms,
+        });
+        self.push_outcome(true);
+        Some(rtt)
+    }
+
+    /// Count probes unanswered for longer than [`PROBE_TIMEOUT`] as lost
+    pub fn expire(&mut self, now: Instant) {
+        let before = self.outstanding.len();
+        self.outstanding.retain(|_, sent| now.saturating_duration_since(*sent) < PROBE_TIMEOUT);
+        for _ in self.outstanding.len()..before {
+            self.push_outcome(false);
+        }
+    }
+
+    fn push_outcome(&mut self, answered: bool) {
+        if self.outcomes.len() >= LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(answered);
+        self.samples += 1;
+    }
+
+    /// Current measurements
+    pub fn quality(&mut self, now: Instant) -> LinkQuality {
+        self.expire(now);
+        let lost = self.outcomes.iter().filter(|answered| !**answered).count();
+        LinkQuality {
+            rtt_ms: self.smoothed_rtt.unwrap_or(0.0),
+            jitter_ms: self.jitter,
+            loss_percent: if self.outcomes.is_empty() {
+                0.0
+            } else {
+                lost as f64 * 100.0 / self.outcomes.len() as f64
+            },
+            samples: self.samples,
+        }
+    }
+}
+
+/// What the agent sees of a session's connection, sent every few seconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QualityReport {
+    /// Agent-measured link to the relay
+    pub link: LinkQuality,
+    /// Average capture and encode time per frame
+    pub encode_ms: f64,
+    /// Target capture frame rate
+    pub capture_fps: u32,
+    /// Target encoder bitrate
+    pub bitrate_kbps: u32,
+    /// Messages waiting in the relay queue across all lanes
+    pub queue_depth: usize,
+    /// Frames dropped by the capture loop or the relay queue
+    pub frames_dropped: u64,
+    /// "Direct" or "Relayed"
+    pub connection_type: String,
+    /// Name of the encoder in use
+    #[serde(default)]
+    pub encoder: String,
+    /// Better encoders that failed their probe, and why
+    #[serde(default)]
+    pub skipped_encoders: Vec<SkippedEncoder>,
+    /// Rate frames actually went out at
+    #[serde(default)]
+    pub throughput_kbps: u32,
+    /// Bandwidth cap in force, if the session has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_cap_kbps: Option<u32>,
+    /// Components the session watchdog is restarting, so the viewer can
+    /// tell a recovering stream from a stalled one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovering: Vec<Component>,
+    /// Codec agreed with the relay and how much it saved
+    #[serde(default)]
+    pub compression: CompressionReport,
+    /// Input events the session received, injected and previewed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<InputStats>,
+}
+
+impl QualityReport {
+    pub fn new(
+        link: LinkQuality,
+        capture: Option<&CaptureStats>,
+        outbound: Option<&OutboundStats>,
+        connection_type: impl ToString,
+    ) -> Self {
+        let mut report = Self {
+            link,
+            connection_type: connection_type.to_string(),
+            ..Self::default()
+        };
+        if let Some(capture) = capture {
+            report.encode_ms = capture.frame_time_ms;
+            report.capture_fps = capture.fps;
+            report.bitrate_kbps = capture.bitrate_kbps;
+            report.frames_dropped = capture.frames_dropped;
+            report.encoder = capture.encoder.clone();
+            report.skipped_encoders = capture.skipped_encoders.clone();
+            report.throughput_kbps = capture.throughput_kbps;
+            report.bandwidth_cap_kbps = capture.bandwidth_cap_kbps;
+        }
+        if let Some(outbound) = outbound {
+            report.queue_depth = outbound.depth.iter().sum();
+            report.frames_dropped += outbound.dropped.iter().sum::<u64>();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::encoder_factory::EncoderKind;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_rtt_and_jitter() {
+        let mut tracker = ProbeTracker::new();
+        let start = Instant::now();
+
+        // Round trips of 100, 120, 80 and 100ms
+        let mut now = start;
+        for rtt in [100, 120, 80, 100] {
+            let sequence = tracker.next_probe(now);
+            assert_eq!(tracker.record_echo(sequence, now + ms(rtt)), Some(ms(rtt)));
+            now += ms(1000);
+        }
+
+        let quality = tracker.quality(now);
+        // 100 -> 102.5 -> 99.69 -> 99.73
+        assert!((quality.rtt_ms - 99.73).abs() < 0.01, "{}", quality.rtt_ms);
+        // Deviations of 20, 40 and 20ms folded in at 1/16
+        let mut jitter = 0.0;
+        for deviation in [20.0, 40.0, 20.0] {
+            jitter += (deviation - jitter) / 16.0;
+        }
+        assert!((quality.jitter_ms - jitter).abs() < 1e-9);
+        assert_eq!(quality.loss_percent, 0.0);
+        assert_eq!(quality.samples, 4);
+    }
+
+    #[test]
+    fn test_loss_and_stray_echoes() {
+        let mut tracker = ProbeTracker::new();
+        let start = Instant::now();
+
+        let answered = tracker.next_probe(start);
+        let lost = tracker.next_probe(start);
+        tracker.record_echo(answered, start + ms(50));
+        assert_eq!(tracker.quality(start + ms(100)).loss_percent, 0.0, "still in flight");
+
+        let later = start + PROBE_TIMEOUT + ms(1);
+        assert_eq!(tracker.quality(later).loss_percent, 50.0);
+        // A late echo doesn't undo the loss or skew the round-trip time
+        assert_eq!(tracker.record_echo(lost, later), None);
+        assert_eq!(tracker.record_echo(999, later), None);
+        assert_eq!(tracker.quality(later).rtt_ms, 50.0);
+
+        // Loss only covers the recent window
+        let mut now = later;
+        for _ in 0..LOSS_WINDOW {
+            let sequence = tracker.next_probe(now);
+            tracker.record_echo(sequence, now + ms(10));
+            now += ms(1000);
+        }
+        assert_eq!(tracker.quality(now).loss_percent, 0.0);
+    }
+
+    #[test]
+    fn test_report_aggregation() {
+        let link = LinkQuality { rtt_ms: 42.0, jitter_ms: 3.0, loss_percent: 10.0, samples: 10 };
+        let capture = CaptureStats {
+            fps: 24,
+            bitrate_kbps: 1500,
+            frame_time_ms: 12.5,
+            frames_dropped: 3,
+            encoder: "Software JPEG Encoder (q=80)".to_string(),
+            skipped_encoders: vec![SkippedEncoder {
+                encoder: EncoderKind::NvencH264,
+                reason: "Encoder not available: nvenc-h264 (not compiled in)".to_string(),
+            }],
+            bandwidth_cap_kbps: Some(1000),
+            throughput_kbps: 940,
+            ..CaptureStats::default()
+        };
+        let outbound = OutboundStats {
+            depth: [1, 2, 5, 0],
+            dropped: [0, 0, 4, 0],
+            ..OutboundStats::default()
+        };
+
+        let report = QualityReport::new(link, Some(&capture), Some(&outbound), "Relayed");
+        assert_eq!(report.link, link);
+        assert_eq!(report.encode_ms, 12.5);
+        assert_eq!(report.capture_fps, 24);
+        assert_eq!(report.bitrate_kbps, 1500);
+        assert_eq!(report.queue_depth, 8);
+        assert_eq!(report.frames_dropped, 7);
+        assert_eq!(report.connection_type, "Relayed");
+        assert_eq!(report.encoder, capture.encoder);
+        assert_eq!(report.skipped_encoders, capture.skipped_encoders);
+        assert_eq!(report.throughput_kbps, 940);
+        assert_eq!(report.bandwidth_cap_kbps, Some(1000));
+
+        // Sessions without a screen stream only report the link
+        let report = QualityReport::new(link, None, None, "Direct");
+        assert_eq!(report.capture_fps, 0);
+        assert_eq!(report.queue_depth, 0);
+    }
+}
@@ -0,0 +1,353 @@
+//! Minimal STUN client for public address discovery and NAT classification
+//!
+//! Implements the binding request of RFC 5389 and, against servers that
+//! advertise an alternate address (RFC 5780), the change-request tests that
+//! tell cone NATs apart. Classification is a pure function of what the probes
+//! observed so it can be tested without a network.
+
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use super::p2p::NATType;
+use crate::error::{Context, GhostLinkError, Result};
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802C;
+
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+
+/// Per-attempt wait for a binding response
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Attempts per binding request before the server counts as unreachable
+const ATTEMPTS: usize = 3;
+
+/// What a binding response told us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindingResponse {
+    /// Our address as the server saw it
+    pub mapped: SocketAddr,
+    /// Alternate server address, present on RFC 5780 servers
+    pub other: Option<SocketAddr>,
+}
+
+/// Results of the discovery probes, input to [`classify`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NatObservations {
+    /// Address the socket is bound to
+    pub local: Option<SocketAddr>,
+    /// Mapping seen by the first server
+    pub mapped: Option<SocketAddr>,
+    /// Mapping seen by a second server, or the first server's alternate address
+    pub mapped_alt: Option<SocketAddr>,
+    /// Whether a reply sent from another IP and port got through
+    pub changed_address_reply: Option<bool>,
+    /// Whether a reply sent from another port got through
+    pub changed_port_reply: Option<bool>,
+}
+
+/// Classify the NAT in front of a socket from probe results
+pub fn classify(observations: &NatObservations) -> NATType {
+    let Some(mapped) = observations.mapped else {
+        // No server answered: UDP is blocked or STUN is unreachable
+        return NATType::Unknown;
+    };
+    if observations.local == Some(mapped) {
+        return NATType::Open;
+    }
+    if observations.mapped_alt.is_some_and(|alt| alt != mapped) {
+        return NATType::Symmetric;
+    }
+
+    match (observations.changed_address_reply, observations.changed_port_reply) {
+        (Some(true), _) => NATType::FullCone,
+        (_, Some(true)) => NATType::RestrictedCone,
+        (_, Some(false)) => NATType::PortRestricted,
+        // Mapping is stable but filtering is unknown; assume the strictest cone
+        _ if observations.mapped_alt.is_some() => NATType::PortRestricted,
+        _ => NATType::Unknown,
+    }
+}
+
+/// Encode a binding request, optionally asking the server to answer from
+/// another IP and/or port
+pub fn encode_binding_request(transaction_id: &[u8; 12], change_ip: bool, change_port: bool) -> Vec<u8> {
+    let mut flags = 0;
+    if change_ip {
+        flags |= CHANGE_IP;
+    }
+    if change_port {
+        flags |= CHANGE_PORT;
+    }
+
+    let attributes_len: u16 = if flags != 0 { 8 } else { 0 };
+    let mut out = Vec::with_capacity(HEADER_LEN + attributes_len as usize);
+    out.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    out.extend_from_slice(&attributes_len.to_be_bytes());
+    out.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    out.extend_from_slice(transaction_id);
+    if flags != 0 {
+        out.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+        out.extend_from_slice(&4u16.to_be_bytes());
+        out.extend_from_slice(&flags.to_be_bytes());
+    }
+    out
+}
+
+/// Parse a binding success response for the given transaction
+pub fn decode_binding_response(data: &[u8], transaction_id: &[u8; 12]) -> Option<BindingResponse> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    let kind = u16::from_be_bytes([data[0], data[1]]);
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if kind != BINDING_SUCCESS
+        || data[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || &data[8..20] != transaction_id
+        || data.len() < HEADER_LEN + length
+    {
+        return None;
+    }
+
+    let mut mapped = None;
+    let mut xor_mapped = None;
+    let mut other = None;
+    let mut attributes = &data[HEADER_LEN..HEADER_LEN + length];
+    while attributes.len() >= 4 {
+        let attr_type = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let attr_len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + attr_len)?;
+        match attr_type {
+            ATTR_MAPPED_ADDRESS => mapped = decode_address(value, None),
+            ATTR_XOR_MAPPED_ADDRESS => xor_mapped = decode_address(value, Some(&data[4..20])),
+            ATTR_OTHER_ADDRESS => other = decode_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to four bytes
+        let padded = (4 + attr_len + 3) & !3;
+        attributes = attributes.get(padded..).unwrap_or_default();
+    }
+
+    Some(BindingResponse { mapped: xor_mapped.or(mapped)?, other })
+}
+
+/// Decode a (XOR-)MAPPED-ADDRESS value; `xor` is the cookie and transaction id
+fn decode_address(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    let family = *value.get(1)?;
+    let mut port_bytes = [*value.get(2)?, *value.get(3)?];
+    let mut address = value.get(4..)?.to_vec();
+    if let Some(mask) = xor {
+        port_bytes[0] ^= mask[0];
+        port_bytes[1] ^= mask[1];
+        address.iter_mut().zip(mask).for_each(|(byte, m)| *byte ^= m);
+    }
+    let port = u16::from_be_bytes(port_bytes);
+
+    let ip = match family {
+        0x01 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(address.get(..4)?).ok()?)),
+        0x02 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(address.get(..16)?).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Send a binding request and wait for its response, retrying a few times.
+///
+/// Returns `None` if the server never answered.
+pub async fn binding(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change_ip: bool,
+    change_port: bool,
+) -> Result<Option<BindingResponse>> {
+    let mut transaction_id = [0u8; 12];
+    SystemRandom::new()
+        .fill(&mut transaction_id)
+        .map_err(|_| GhostLinkError::Other("Failed to generate a STUN transaction id".to_string()))?;
+    let request = encode_binding_request(&transaction_id, change_ip, change_port);
+    let mut buf = [0u8; 512];
+    // A dual-stack socket reaches IPv4 servers at their v4-mapped address
+    let local = socket.local_addr().context("STUN socket has no address")?;
+    let server = crate::network::socket_target(local, server)
+        .ok_or_else(|| GhostLinkError::Other(format!("STUN server {} is IPv6 but the socket is not", server)))?;
+
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&request, server).await.context("Failed to send STUN request")?;
+        let wait = async {
+            loop {
+                let (len, _) = socket.recv_from(&mut buf).await?;
+                if let Some(response) = decode_binding_response(&buf[..len], &transaction_id) {
+                    return Ok::<_, std::io::Error>(response);
+                }
+            }
+        };
+        match timeout(ATTEMPT_TIMEOUT, wait).await {
+            Ok(result) => return Ok(Some(result.context("STUN receive failed")?)),
+            Err(_) => continue,
+        }
+    }
+    Ok(None)
+}
+
+/// Discover the public address of `socket` and the NAT type in front of it.
+///
+/// Must run before the socket carries other traffic, since responses are
+/// read from it directly.
+pub async fn discover(socket: &UdpSocket, local: SocketAddr, servers: &[SocketAddr]) -> (Option<SocketAddr>, NATType) {
+    let mut observations = NatObservations { local: Some(local), ..Default::default() };
+    let mut responses = Vec::new();
+
+    for &server in servers {
+        match binding(socket, server, false, false).await {
+            Ok(Some(response)) => {
+                responses.push(response);
+                if responses.len() == 2 {
+                    break;
+                }
+            }
+            Ok(None) => debug!("STUN server {} did not answer", server),
+            Err(e) => warn!("STUN request to {} failed: {}", server, e),
+        }
+    }
+
+    let Some(first) = responses.first().copied() else {
+        return (None, classify(&observations));
+    };
+    observations.mapped = Some(first.mapped);
+    observations.mapped_alt = responses.get(1).map(|response| response.mapped);
+
+    // Filtering tests need a server with an alternate address to answer from
+    if let (Some(other), Some(server)) = (first.other, servers.first()) {
+        if observations.mapped_alt.is_none() {
+            if let Ok(Some(response)) = binding(socket, other, false, false).await {
+                observations.mapped_alt = Some(response.mapped);
+            }
+        }
+        observations.changed_address_reply = binding(socket, *server, true, true).await.ok().map(|r| r.is_some());
+        observations.changed_port_reply = binding(socket, *server, false, true).await.ok().map(|r| r.is_some());
+    }
+
+    let nat_type = classify(&observations);
+    debug!("NAT observations {:?} classified as {:?}", observations, nat_type);
+    (Some(first.mapped), nat_type)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Encode a binding success carrying `mapped` as XOR-MAPPED-ADDRESS
+    pub(crate) fn binding_response(request: &[u8], mapped: SocketAddr) -> Option<Vec<u8>> {
+        if request.len() < HEADER_LEN || u16::from_be_bytes([request[0], request[1]]) != BINDING_REQUEST {
+            return None;
+        }
+        let SocketAddr::V4(v4) = mapped else { return None };
+        let cookie = MAGIC_COOKIE.to_be_bytes();
+        let port = v4.port() ^ (MAGIC_COOKIE >> 16) as u16;
+        let mut ip = v4.ip().octets();
+        ip.iter_mut().zip(cookie).for_each(|(byte, m)| *byte ^= m);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        out.extend_from_slice(&12u16.to_be_bytes());
+        out.extend_from_slice(&request[4..20]);
+        out.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+        out.extend_from_slice(&8u16.to_be_bytes());
+        out.extend_from_slice(&[0, 0x01]);
+        out.extend_from_slice(&port.to_be_bytes());
+        out.extend_from_slice(&ip);
+        Some(out)
+    }
+
+    /// STUN server on localhost that reports each sender's address
+    pub(crate) async fn spawn_stun_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                if let Some(response) = binding_response(&buf[..len], from) {
+                    let _ = socket.send_to(&response, from).await;
+                }
+            }
+        });
+        addr
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_classify_nat_types() {
+        let local = Some(addr("192.168.1.10:40000"));
+        let mapped = Some(addr("203.0.113.5:40000"));
+
+        assert_eq!(classify(&NatObservations { local, ..Default::default() }), NATType::Unknown);
+        assert_eq!(classify(&NatObservations { local, mapped: local, ..Default::default() }), NATType::Open);
+        assert_eq!(
+            classify(&NatObservations { local, mapped, mapped_alt: Some(addr("203.0.113.5:41234")), ..Default::default() }),
+            NATType::Symmetric
+        );
+        assert_eq!(
+            classify(&NatObservations { local, mapped, mapped_alt: mapped, changed_address_reply: Some(true), changed_port_reply: Some(true) }),
+            NATType::FullCone
+        );
+        assert_eq!(
+            classify(&NatObservations { local, mapped, mapped_alt: mapped, changed_address_reply: Some(false), changed_port_reply: Some(true) }),
+            NATType::RestrictedCone
+        );
+        assert_eq!(
+            classify(&NatObservations { local, mapped, mapped_alt: mapped, changed_address_reply: Some(false), changed_port_reply: Some(false) }),
+            NATType::PortRestricted
+        );
+        // Two servers agree but neither supports the filtering tests
+        assert_eq!(classify(&NatObservations { local, mapped, mapped_alt: mapped, ..Default::default() }), NATType::PortRestricted);
+        // A single server can't tell cone from symmetric
+        assert_eq!(classify(&NatObservations { local, mapped, ..Default::default() }), NATType::Unknown);
+    }
+
+    #[test]
+    fn test_binding_round_trip() {
+        let transaction_id = [7u8; 12];
+        let request = encode_binding_request(&transaction_id, false, false);
+        assert_eq!(request.len(), HEADER_LEN);
+
+        let response = binding_response(&request, addr("198.51.100.7:5555")).unwrap();
+        let decoded = decode_binding_response(&response, &transaction_id).unwrap();
+        assert_eq!(decoded.mapped, addr("198.51.100.7:5555"));
+        assert_eq!(decoded.other, None);
+
+        // Responses to another transaction are ignored
+        assert!(decode_binding_response(&response, &[8u8; 12]).is_none());
+        for len in 0..response.len() {
+            assert!(decode_binding_response(&response[..len], &transaction_id).is_none());
+        }
+
+        let change = encode_binding_request(&transaction_id, true, true);
+        assert_eq!(&change[HEADER_LEN..], &[0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x00, 0x06]);
+    }
+
+    #[tokio::test]
+    async fn test_discover_against_local_server() {
+        let server = spawn_stun_server().await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = socket.local_addr().unwrap();
+
+        let (public, nat_type) = discover(&socket, local, &[server]).await;
+        assert_eq!(public, Some(local));
+        assert_eq!(nat_type, NATType::Open);
+    }
+}
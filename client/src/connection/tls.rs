@@ -0,0 +1,443 @@
+//! TLS for the relay connection
+//!
+//! Without any TLS settings the agent trusts what the system trusts. Setting
+//! `ca_bundle_path` trusts a private CA on top of the system roots, and
+//! `pinned_cert_sha256` additionally requires the server's certificate to
+//! carry one of the pinned public keys. `danger_accept_invalid_certs` skips
+//! chain validation for lab setups with throwaway certificates; pins are
+//! still enforced when it is set. A proxy, when one applies (see
+//! [`proxy`](super::proxy)), carries the connection before TLS starts.
+
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{client_async_tls_with_config, connect_async, connect_async_tls_with_config, Connector};
+use tracing::{info, warn};
+use url::Url;
+
+use super::proxy::{self, Proxy, ProxySettings};
+use super::WsStream;
+use crate::config::ClientConfig;
+use crate::error::{ConnectionError, ConnectionErrorKind, Context, GhostLinkError, Result};
+
+/// Open the relay WebSocket at `url` with the TLS and proxy settings of
+/// `config`
+///
+/// Failures come back as [`ConnectionError`]s whose kind tells a name that
+/// does not resolve from a server that is down, a proxy in the way or a
+/// certificate problem.
+pub async fn connect(config: &ClientConfig, url: &Url) -> Result<(WsStream, Response)> {
+    let connector = connector(config)?;
+    let proxies = ProxySettings::from_config(config);
+    open(url, proxies.for_url(url), connector).await
+}
+
+/// Open the WebSocket at `url`, through `proxy` when given, with the TLS of
+/// `connector` or the platform's
+pub async fn open(url: &Url, proxy: Option<&Proxy>, connector: Option<Connector>) -> Result<(WsStream, Response)> {
+    let connected = match proxy {
+        Some(proxy) => {
+            // The proxy resolves the server's name, which the agent may not
+            let (host, port) = target(url)?;
+            info!("Connecting to {} through proxy {}", url, proxy);
+            let stream = proxy::tunnel(proxy, host, port)
+                .await
+                .map_err(|e| failed(url, e.kind(), format!("through proxy {}: {}", proxy, e)))?;
+            client_async_tls_with_config(url, stream, None, connector).await
+        }
+        None => {
+            resolve(url).await?;
+            match connector {
+                Some(connector) => connect_async_tls_with_config(url, None, false, Some(connector)).await,
+                None => connect_async(url).await,
+            }
+        }
+    };
+    connected.map_err(|e| connect_failure(url, e).into())
+}
+
+/// Host and port a connection to `url` goes to
+pub fn target(url: &Url) -> std::result::Result<(&str, u16), ConnectionError> {
+    let invalid = |reason: &str| ConnectionError::InvalidServerUrl { url: url.to_string(), reason: reason.to_string() };
+    let host = url.host_str().ok_or_else(|| invalid("no host"))?;
+    let port = url.port_or_known_default().ok_or_else(|| invalid("unsupported scheme"))?;
+    Ok((host, port))
+}
+
+/// Look the server's host up first, so a name that does not resolve is
+/// told apart from a server that does not answer
+async fn resolve(url: &Url) -> Result<()> {
+    let (host, port) = target(url)?;
+    match tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port)).await {
+        Ok(mut addrs) => match addrs.next() {
+            Some(_) => Ok(()),
+            None => Err(failed(url, ConnectionErrorKind::Dns, format!("{} has no addresses", host)).into()),
+        },
+        Err(e) => Err(failed(url, ConnectionErrorKind::Dns, format!("{}: {}", host, e)).into()),
+    }
+}
+
+fn failed(url: &Url, kind: ConnectionErrorKind, reason: String) -> ConnectionError {
+    ConnectionError::ConnectionFailed { kind, url: url.to_string(), reason }
+}
+
+/// Sort a failed WebSocket connect by the stage that failed
+fn connect_failure(url: &Url, error: WsError) -> ConnectionError {
+    if let Some(presented) = pin_mismatch(&error) {
+        return ConnectionError::CertificatePinMismatch {
+            host: url.host_str().unwrap_or_default().to_string(),
+            presented,
+        };
+    }
+    let kind = match &error {
+        WsError::Url(e) => {
+            return ConnectionError::InvalidServerUrl { url: url.to_string(), reason: e.to_string() };
+        }
+        WsError::Io(io) if io.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) => ConnectionErrorKind::Tls,
+        WsError::Io(io) => match io.kind() {
+            std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof => ConnectionErrorKind::Closed,
+            _ => ConnectionErrorKind::Unreachable,
+        },
+        WsError::Tls(_) => ConnectionErrorKind::Tls,
+        WsError::Http(response) if matches!(response.status().as_u16(), 401 | 403) => ConnectionErrorKind::AuthRejected,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => ConnectionErrorKind::Closed,
+        _ => ConnectionErrorKind::Handshake,
+    };
+    failed(url, kind, error.to_string())
+}
+
+/// rustls connector for the TLS settings of `config`, or `None` to keep the
+/// platform's default TLS
+pub fn connector(config: &ClientConfig) -> Result<Option<Connector>> {
+    if config.ca_bundle_path.is_none() && config.pinned_cert_sha256.is_empty() && !config.danger_accept_invalid_certs {
+        return Ok(None);
+    }
+
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            let (_, ignored) = roots.add_parsable_certificates(certs);
+            if ignored > 0 {
+                warn!("Ignored {} unparsable system root certificates", ignored);
+            }
+        }
+        Err(e) => warn!("Failed to load system root certificates: {}", e),
+    }
+    if let Some(path) = &config.ca_bundle_path {
+        let certs = load_certificates(path)?;
+        info!("Trusting {} certificates from {}", certs.len(), path.display());
+        for cert in certs {
+            roots.add(cert).with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+        }
+    }
+
+    let pins = config.pinned_cert_sha256.iter()
+        .map(|pin| parse_pin(pin))
+        .collect::<Result<Vec<_>>>()?;
+    if config.danger_accept_invalid_certs {
+        warn!("TLS certificate validation is DISABLED (danger_accept_invalid_certs); anyone between the agent and {} can impersonate the relay", config.server_url);
+    }
+
+    let verifier = PinningVerifier {
+        inner: WebPkiServerVerifier::builder(Arc::new(roots))
+            .build()
+            .map_err(|e| GhostLinkError::Other(format!("Failed to set up certificate validation: {}", e)))?,
+        pins,
+        accept_invalid: config.danger_accept_invalid_certs,
+    };
+    let tls = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Some(Connector::Rustls(Arc::new(tls))))
+}
+
+/// Certificates in the PEM file at `path`
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid PEM in {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(GhostLinkError::Other(format!("No certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// SHA-256 of a certificate's SubjectPublicKeyInfo, the value pins hold
+pub fn spki_sha256(cert_der: &[u8]) -> Result<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| GhostLinkError::Other(format!("Invalid certificate: {}", e)))?;
+    Ok(Sha256::digest(cert.public_key().raw).into())
+}
+
+/// Parse a pin given as hex (colons allowed) or base64
+pub fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let pin = pin.trim();
+    let hex_digits: String = pin.chars().filter(|c| *c != ':').collect();
+    let bytes = match hex::decode(&hex_digits) {
+        Ok(bytes) => bytes,
+        Err(_) => base64::engine::general_purpose::STANDARD.decode(pin)
+            .map_err(|_| GhostLinkError::Other(format!("Pin '{}' is neither hex nor base64", pin)))?,
+    };
+    bytes.try_into()
+        .map_err(|_| GhostLinkError::Other(format!("Pin '{}' is not a SHA-256 hash", pin)))
+}
+
+/// SPKI hash the server presented, if the connection failed on the pins
+fn pin_mismatch(error: &tokio_tungstenite::tungstenite::Error) -> Option<String> {
+    let tokio_tungstenite::tungstenite::Error::Io(io) = error else {
+        return None;
+    };
+    match io.get_ref()?.downcast_ref::<rustls::Error>()? {
+        rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(inner))) => {
+            inner.downcast_ref::<PinMismatch>().map(|mismatch| mismatch.presented.clone())
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct PinMismatch {
+    /// Hex SPKI hash of the certificate the server presented
+    presented: String,
+}
+
+impl std::fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "certificate key {} is not pinned", self.presented)
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// Validates the chain as usual (unless told not to), then checks the
+/// leaf's public key against the pins
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+    accept_invalid: bool,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if !self.accept_invalid {
+            self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if !self.pins.is_empty() {
+            let hash = spki_sha256(end_entity)
+                .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+            if !self.pins.contains(&hash) {
+                return Err(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(
+                    Arc::new(PinMismatch { presented: hex::encode(hash) }),
+                ))));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use rustls::pki_types::PrivateKeyDer;
+    use tokio::net::TcpListener;
+
+    struct TestPki {
+        ca_pem: String,
+        leaf_der: Vec<u8>,
+        key_der: Vec<u8>,
+    }
+
+    /// A CA and a `localhost` certificate it signed
+    fn pki() -> TestPki {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let leaf = Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+        TestPki {
+            ca_pem: ca.serialize_pem().unwrap(),
+            leaf_der: leaf.serialize_der_with_signer(&ca).unwrap(),
+            key_der: leaf.serialize_private_key_der(),
+        }
+    }
+
+    /// WebSocket server presenting the `pki` leaf; returns its URL
+    async fn serve(pki: &TestPki) -> Url {
+        let tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(pki.leaf_der.clone())],
+                PrivateKeyDer::try_from(pki.key_der.clone()).unwrap(),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let _ = tokio_tungstenite::accept_async(stream).await;
+                    }
+                });
+            }
+        });
+        Url::parse(&format!("wss://localhost:{}/relay/ws", port)).unwrap()
+    }
+
+    fn config(url: &Url, dir: &tempfile::TempDir, pki: &TestPki) -> ClientConfig {
+        let ca_path = dir.path().join("ca.pem");
+        std::fs::write(&ca_path, &pki.ca_pem).unwrap();
+        let mut config = ClientConfig::new(url.to_string(), Some("Test Device".to_string())).unwrap();
+        config.ca_bundle_path = Some(ca_path);
+        config
+    }
+
+    #[tokio::test]
+    async fn test_private_ca_is_trusted() {
+        let pki = pki();
+        let url = serve(&pki).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        // A matching pin does not make an unknown CA trusted
+        let mut untrusting = config(&url, &dir, &pki);
+        untrusting.ca_bundle_path = None;
+        untrusting.pinned_cert_sha256 = vec![hex::encode(spki_sha256(&pki.leaf_der).unwrap())];
+        assert!(connect(&untrusting, &url).await.is_err());
+
+        assert!(connect(&config(&url, &dir, &pki), &url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_matching_pin_connects() {
+        let pki = pki();
+        let url = serve(&pki).await;
+        let dir = tempfile::tempdir().unwrap();
+        let hash = spki_sha256(&pki.leaf_der).unwrap();
+
+        let mut config = config(&url, &dir, &pki);
+        config.pinned_cert_sha256 = vec![
+            hex::encode([7u8; 32]),
+            base64::engine::general_purpose::STANDARD.encode(hash),
+        ];
+        assert!(connect(&config, &url).await.is_ok());
+
+        // Lab mode skips the chain, not the pins
+        config.ca_bundle_path = None;
+        config.danger_accept_invalid_certs = true;
+        assert!(connect(&config, &url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pin_mismatch_fails_with_connection_error() {
+        let pki = pki();
+        let url = serve(&pki).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut config = config(&url, &dir, &pki);
+        config.pinned_cert_sha256 = vec![hex::encode([7u8; 32])];
+        match connect(&config, &url).await.unwrap_err() {
+            GhostLinkError::Connection(ConnectionError::CertificatePinMismatch { host, presented }) => {
+                assert_eq!(host, "localhost");
+                assert_eq!(presented, hex::encode(spki_sha256(&pki.leaf_der).unwrap()));
+            }
+            other => panic!("expected a pin mismatch, got {:?}", other),
+        }
+    }
+
+    fn failure_kind(error: GhostLinkError) -> Option<ConnectionErrorKind> {
+        match error {
+            GhostLinkError::Connection(e) => e.kind(),
+            other => panic!("expected a connection error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_failures_are_told_apart() {
+        let config = ClientConfig::new("ws://localhost/relay/ws".to_string(), Some("Test Device".to_string())).unwrap();
+
+        // Nothing listens on a port that was just freed
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let refused = Url::parse(&format!("ws://127.0.0.1:{}/relay/ws", port)).unwrap();
+        let error = connect(&config, &refused).await.unwrap_err();
+        assert!(error.is_retryable());
+        assert_eq!(failure_kind(error), Some(ConnectionErrorKind::Unreachable));
+
+        // `.invalid` never resolves
+        let unknown = Url::parse("wss://relay.ghostlink.invalid/relay/ws").unwrap();
+        assert_eq!(failure_kind(connect(&config, &unknown).await.unwrap_err()), Some(ConnectionErrorKind::Dns));
+
+        let hostless = Url::parse("unix:/run/relay.sock").unwrap();
+        let error = connect(&config, &hostless).await.unwrap_err();
+        assert!(!error.is_retryable());
+        assert!(matches!(error, GhostLinkError::Connection(ConnectionError::InvalidServerUrl { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_pin_mismatch_is_not_retried() {
+        let pki = pki();
+        let url = serve(&pki).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut config = config(&url, &dir, &pki);
+        config.pinned_cert_sha256 = vec![hex::encode([7u8; 32])];
+        let error = connect(&config, &url).await.unwrap_err();
+        assert_eq!(error.exit_code(), 78);
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn test_parse_pin() {
+        let hash = [0xabu8; 32];
+        let colons = hash.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":");
+        assert_eq!(parse_pin(&colons).unwrap(), hash);
+        assert_eq!(parse_pin(&base64::engine::general_purpose::STANDARD.encode(hash)).unwrap(), hash);
+        assert!(parse_pin("abcd").is_err());
+        assert!(parse_pin("not a pin").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Agent end of the relay's UDP lane
+//!
+//! When the relay offers a session an association, the agent says hello from
+//! a fresh UDP socket until the relay acknowledges it. If nothing comes back
+//! within the probe window, UDP counts as blocked and frames stay on the
+//! WebSocket. Once up, frames are cut into shards with parity (see
+//! `datagram`) so a lost datagram costs neither a retransmit nor the frames
+//! queued behind it, and keepalives tell both ends the path still works.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+use super::datagram::{self, FecEncoder, LaneMonitor, LaneStatus, LaneTimings, LaneToken, PacketKind};
+use crate::error::{Context, GhostLinkError, Result};
+
+/// How often the hello is repeated while waiting for the relay
+const HELLO_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Decode the association token from a `UdpLaneOffer`
+pub fn parse_token(token: &str) -> Result<LaneToken> {
+    BASE64.decode(token)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("Malformed UDP lane token")
+}
+
+/// An association with the relay's UDP port
+pub struct UdpLane {
+    socket: Arc<UdpSocket>,
+    token: LaneToken,
+    timings: LaneTimings,
+    encoder: Mutex<FecEncoder>,
+    monitor: Arc<Mutex<LaneMonitor>>,
+    receiver: JoinHandle<()>,
+}
+
+impl UdpLane {
+    /// Say hello to the relay until it answers; fails when the probe window
+    /// passes in silence, which means UDP is blocked on the way
+    pub async fn connect(relay: SocketAddr, token: LaneToken, timings: LaneTimings) -> Result<Self> {
+        let local: SocketAddr = match relay {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await.context("Failed to bind the UDP lane socket")?;
+        socket.connect(relay).await.context("Failed to address the relay's UDP port")?;
+
+        let hello = datagram::encode_packet(PacketKind::Hello, &token, &[]);
+        let started = Instant::now();
+        let mut buf = [0u8; 256];
+        loop {
+            if started.elapsed() >= timings.probe_window {
+                return Err(GhostLinkError::Other(format!("No answer from {} within {:?}, UDP looks blocked", relay, timings.probe_window)));
+            }
+            // Refused or unreachable ports surface as errors; keep trying until the window passes
+            if let Err(e) = socket.send(&hello).await {
+                debug!("UDP lane hello to {} failed: {}", relay, e);
+            }
+            let acked = async {
+                loop {
+                    let len = socket.recv(&mut buf).await?;
+                    if let Some((PacketKind::HelloAck, answered, _)) = datagram::decode_packet(&buf[..len]) {
+                        if answered == token {
+                            return Ok::<_, std::io::Error>(());
+                        }
+                    }
+                }
+            };
+            match timeout(HELLO_INTERVAL, acked).await {
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => {
+                    debug!("UDP lane socket error while probing {}: {}", relay, e);
+                    tokio::time::sleep(HELLO_INTERVAL).await;
+                }
+                Err(_) => {}
+            }
+        }
+        info!("UDP lane to {} is up", relay);
+
+        let mut monitor = LaneMonitor::new(timings, started);
+        monitor.heard(Instant::now());
+        let monitor = Arc::new(Mutex::new(monitor));
+        let socket = Arc::new(socket);
+        let receiver = tokio::spawn(Self::receive(socket.clone(), token, monitor.clone()));
+
+        Ok(Self {
+            socket,
+            token,
+            timings,
+            encoder: Mutex::new(FecEncoder::default()),
+            monitor,
+            receiver,
+        })
+    }
+
+    /// Note every answer from the relay; nothing else comes back on the lane
+    async fn receive(socket: Arc<UdpSocket>, token: LaneToken, monitor: Arc<Mutex<LaneMonitor>>) {
+        let mut buf = [0u8; 256];
+        loop {
+            let len = match socket.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(e) => {
+                    // ICMP errors show up here; the monitor decides whether the lane is gone
+                    debug!("UDP lane receive failed: {}", e);
+                    tokio::time::sleep(HELLO_INTERVAL).await;
+                    continue;
+                }
+            };
+            if let Some((_, answered, _)) = datagram::decode_packet(&buf[..len]) {
+                if answered == token {
+                    monitor.lock().unwrap().heard(Instant::now());
+                }
+            }
+        }
+    }
+
+    pub fn timings(&self) -> LaneTimings {
+        self.timings
+    }
+
+    pub fn status(&self) -> LaneStatus {
+        self.monitor.lock().unwrap().status(Instant::now())
+    }
+
+    /// Send one frame as shards with parity
+    pub async fn send_frame(&self, frame: &[u8], keyframe: bool) -> Result<()> {
+        if frame.is_empty() || frame.len() > datagram::MAX_FRAME_LEN {
+            return Err(GhostLinkError::Other(format!("Frame of {} bytes does not fit the UDP lane", frame.len())));
+        }
+        let shards = self.encoder.lock().unwrap().encode(frame, keyframe);
+        for shard in shards {
+            let packet = datagram::encode_packet(PacketKind::Shard, &self.token, &shard.encode());
+            self.socket.send(&packet).await.context("Failed to send on the UDP lane")?;
+        }
+        Ok(())
+    }
+
+    /// Ask the relay to echo, keeping the NAT mapping open
+    pub async fn keepalive(&self) -> Result<()> {
+        let packet = datagram::encode_packet(PacketKind::Keepalive, &self.token, &[]);
+        self.socket.send(&packet).await.context("Failed to send UDP lane keepalive")?;
+        Ok(())
+    }
+}
+
+impl Drop for UdpLane {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::connection::datagram::{JitterBuffer, Shard};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Relay stand-in that acknowledges hellos and echoes keepalives while
+    /// `answering`, drops every `drop_every`th shard and hands the frames it
+    /// could put back together to the returned channel
+    pub(crate) async fn spawn_lane_relay(
+        answering: Arc<AtomicBool>,
+        drop_every: usize,
+    ) -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (frames_tx, frames_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buffer = JitterBuffer::default();
+            let mut buf = [0u8; 2048];
+            let mut shards = 0;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let Some((kind, token, body)) = datagram::decode_packet(&buf[..len]) else { continue };
+                match kind {
+                    PacketKind::Hello | PacketKind::Keepalive if answering.load(Ordering::SeqCst) => {
+                        let reply = if kind == PacketKind::Hello { PacketKind::HelloAck } else { PacketKind::Keepalive };
+                        socket.send_to(&datagram::encode_packet(reply, &token, &[]), from).await.unwrap();
+                    }
+                    PacketKind::Shard => {
+                        shards += 1;
+                        if drop_every > 0 && shards % drop_every == 0 {
+                            continue;
+                        }
+                        buffer.push(Shard::decode(body).unwrap(), Instant::now());
+                        for frame in buffer.poll(Instant::now()) {
+                            let _ = frames_tx.send(frame.data);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (addr, frames_rx)
+    }
+
+    fn quick() -> LaneTimings {
+        LaneTimings {
+            probe_window: Duration::from_millis(600),
+            keepalive_interval: Duration::from_millis(50),
+            silence_timeout: Duration::from_millis(300),
+        }
+    }
+
+    #[test]
+    fn test_parse_token() {
+        assert_eq!(parse_token(&BASE64.encode([3u8; 16])).unwrap(), [3u8; 16]);
+        assert!(parse_token("not base64!").is_err());
+        assert!(parse_token(&BASE64.encode([3u8; 8])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_udp_fails_within_probe_window() {
+        // Every datagram towards the relay is lost
+        let (relay, _) = spawn_lane_relay(Arc::new(AtomicBool::new(false)), 0).await;
+        let started = Instant::now();
+        let result = UdpLane::connect(relay, [1; 16], quick()).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_frames_cross_a_lossy_lane() {
+        // Losing one datagram in nine is covered by a parity shard per eight
+        let (relay, mut frames) = spawn_lane_relay(Arc::new(AtomicBool::new(true)), 9).await;
+        let lane = UdpLane::connect(relay, [2; 16], quick()).await.unwrap();
+        assert_eq!(lane.status(), LaneStatus::Up);
+
+        let sent: Vec<Vec<u8>> = (0..3u8).map(|seed| (0..20_000).map(|i| (i as u8) ^ seed).collect()).collect();
+        for (index, frame) in sent.iter().enumerate() {
+            lane.send_frame(frame, index == 0).await.unwrap();
+        }
+        for frame in &sent {
+            let received = timeout(Duration::from_secs(2), frames.recv()).await.unwrap();
+            assert_eq!(received.as_ref(), Some(frame));
+        }
+        assert!(lane.send_frame(&[], false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lane_fails_when_relay_goes_quiet() {
+        let answering = Arc::new(AtomicBool::new(true));
+        let (relay, _) = spawn_lane_relay(answering.clone(), 0).await;
+        let lane = UdpLane::connect(relay, [3; 16], quick()).await.unwrap();
+
+        lane.keepalive().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        lane.keepalive().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(lane.status(), LaneStatus::Up);
+
+        answering.store(false, Ordering::SeqCst);
+        for _ in 0..8 {
+            lane.keepalive().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(lane.status(), LaneStatus::Failed);
+    }
+}
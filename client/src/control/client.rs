@@ -0,0 +1,76 @@
+//! The tray's end of the control API
+
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::time::{timeout, Duration};
+
+use crate::error::{Context, GhostLinkError, Result};
+
+use super::protocol::{read_line, write_line, ControlRequest, ControlResponse};
+
+/// How long a request may take; joining waits for the relay
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Send `request` to the running agent, at the first endpoint it listens on
+pub async fn request(request: &ControlRequest) -> Result<ControlResponse> {
+    let endpoints = super::endpoints();
+    for endpoint in &endpoints {
+        if let Some(response) = request_at(endpoint, request).await? {
+            return Ok(response);
+        }
+    }
+    let tried: Vec<String> = endpoints.iter().map(|endpoint| endpoint.display().to_string()).collect();
+    Err(GhostLinkError::Other(format!("The GhostLink agent is not running (nothing listens on {})", tried.join(" or "))))
+}
+
+/// Send `request` to the agent at `endpoint`; `None` if nothing listens there
+pub async fn request_at(endpoint: &Path, request: &ControlRequest) -> Result<Option<ControlResponse>> {
+    let Some(stream) = connect(endpoint).await? else {
+        return Ok(None);
+    };
+    timeout(REQUEST_TIMEOUT, exchange(stream, request))
+        .await
+        .map_err(|_| GhostLinkError::Other(format!("The agent did not answer within {}s", REQUEST_TIMEOUT.as_secs())))?
+        .map(Some)
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite>(stream: S, request: &ControlRequest) -> Result<ControlResponse> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    write_line(&mut writer, request).await?;
+    read_line(&mut BufReader::new(reader))
+        .await?
+        .context("The agent closed the connection without answering")
+}
+
+#[cfg(unix)]
+async fn connect(path: &Path) -> Result<Option<tokio::net::UnixStream>> {
+    use std::io::ErrorKind;
+
+    match tokio::net::UnixStream::connect(path).await {
+        Ok(stream) => Ok(Some(stream)),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            Err(e).with_context(|| format!("Not allowed to talk to the agent at {}", path.display()))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to connect to {}", path.display())),
+    }
+}
+
+#[cfg(windows)]
+async fn connect(name: &Path) -> Result<Option<tokio::net::windows::named_pipe::NamedPipeClient>> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    use windows::Win32::Foundation::{ERROR_FILE_NOT_FOUND, ERROR_PIPE_BUSY};
+
+    loop {
+        match ClientOptions::new().open(name) {
+            Ok(pipe) => return Ok(Some(pipe)),
+            Err(e) if e.raw_os_error() == Some(ERROR_FILE_NOT_FOUND.0 as i32) => return Ok(None),
+            // Another client has the free instance; the agent makes a new one
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}", name.display())),
+        }
+    }
+}
+
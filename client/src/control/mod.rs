@@ -0,0 +1,72 @@
+//! Local control API for the end user
+//!
+//! `ghostlink-client tray` shows the person at the machine whether the
+//! agent is connected and which technicians have sessions open, and lets
+//! them end a session, pause remote access or join a support code. It does
+//! not connect to the relay itself: the running agent listens on a Unix
+//! socket, or a named pipe on Windows, and answers [`ControlRequest`]s with
+//! the same state it runs sessions with. Policy decides which sessions the
+//! end user may end and whether they may pause.
+
+use std::path::PathBuf;
+use tokio::sync::oneshot;
+
+pub mod client;
+pub mod pause;
+pub mod protocol;
+pub mod server;
+
+pub use protocol::{AgentStatus, ControlRequest, ControlResponse, SessionSummary};
+
+/// A request from the tray, for the agent's event loop to answer
+#[derive(Debug)]
+pub struct ControlCommand {
+    pub request: ControlRequest,
+    pub reply: oneshot::Sender<ControlResponse>,
+}
+
+/// Socket of an agent running as root or a service account
+#[cfg(target_os = "linux")]
+const SYSTEM_SOCKET: &str = "/run/ghostlink/control.sock";
+#[cfg(all(unix, not(target_os = "linux")))]
+const SYSTEM_SOCKET: &str = "/var/run/ghostlink/control.sock";
+
+#[cfg(windows)]
+const PIPE_NAME: &str = r"\\.\pipe\ghostlink-control";
+
+/// Socket of an agent running as the current user
+#[cfg(unix)]
+fn user_socket() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("ghostlink")
+        .join("control.sock")
+}
+
+/// Where this agent listens
+#[cfg(unix)]
+pub fn endpoint() -> PathBuf {
+    // SAFETY: plain syscall without arguments
+    if unsafe { libc::geteuid() } == 0 {
+        PathBuf::from(SYSTEM_SOCKET)
+    } else {
+        user_socket()
+    }
+}
+
+#[cfg(windows)]
+pub fn endpoint() -> PathBuf {
+    PathBuf::from(PIPE_NAME)
+}
+
+/// Where the tray looks for the agent: the installed service first, then
+/// one the user started
+#[cfg(unix)]
+pub fn endpoints() -> Vec<PathBuf> {
+    vec![PathBuf::from(SYSTEM_SOCKET), user_socket()]
+}
+
+#[cfg(windows)]
+pub fn endpoints() -> Vec<PathBuf> {
+    vec![PathBuf::from(PIPE_NAME)]
+}
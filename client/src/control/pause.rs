@@ -0,0 +1,206 @@
+//! Remote access paused by the end user
+//!
+//! While paused the agent ends the sessions it runs and refuses new ones,
+//! and the relay shows the device as "access paused". The pause is kept in
+//! `pause.json` next to the config, so restarting the agent does not lift
+//! it. Policy decides whether the end user may pause and for how long; a
+//! pause is shortened or lifted when a new policy allows less.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::config::ClientConfig;
+use crate::error::{Context, Result};
+
+/// Why a pause was not started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PauseRefused {
+    /// Policy does not let the end user pause
+    NotAllowed,
+    /// Longer than policy allows, in hours
+    TooLong(u32),
+    ZeroHours,
+}
+
+impl std::fmt::Display for PauseRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PauseRefused::NotAllowed => f.write_str("Your organization does not allow pausing remote access"),
+            PauseRefused::TooLong(max) => write!(f, "Remote access may be paused for at most {} hours", max),
+            PauseRefused::ZeroHours => f.write_str("A pause must last at least an hour"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Paused {
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+}
+
+/// Whether remote access is paused, and until when
+#[derive(Debug, Default)]
+pub struct AccessPause {
+    paused: Option<Paused>,
+    store: Option<PauseStore>,
+}
+
+impl AccessPause {
+    /// A pause kept in `store`, starting from the one stored there
+    pub fn with_store(store: PauseStore) -> Self {
+        Self { paused: store.load(), store: Some(store) }
+    }
+
+    /// When the pause ends, if access is paused at `now`
+    pub fn until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.paused.map(|paused| paused.until).filter(|&until| until > now)
+    }
+
+    /// Pause for `hours` from `now`, within `limit` hours; returns when the
+    /// pause ends
+    pub fn pause(&mut self, hours: u32, limit: Option<u32>, now: DateTime<Utc>) -> std::result::Result<DateTime<Utc>, PauseRefused> {
+        let max = limit.ok_or(PauseRefused::NotAllowed)?;
+        if hours == 0 {
+            return Err(PauseRefused::ZeroHours);
+        }
+        if hours > max {
+            return Err(PauseRefused::TooLong(max));
+        }
+        let until = now + Duration::hours(hours as i64);
+        self.set(Some(Paused { since: now, until }));
+        Ok(until)
+    }
+
+    /// Lift the pause; returns whether access was paused
+    pub fn resume(&mut self, now: DateTime<Utc>) -> bool {
+        let paused = self.until(now).is_some();
+        self.set(None);
+        paused
+    }
+
+    /// Hold a running pause to a new `limit`: lifted when the end user may
+    /// no longer pause, shortened when it runs longer than allowed. Returns
+    /// whether the pause changed.
+    pub fn hold_to(&mut self, limit: Option<u32>, now: DateTime<Utc>) -> bool {
+        let Some(paused) = self.paused.filter(|paused| paused.until > now) else {
+            return false;
+        };
+        let held = limit.map(|max| Paused { until: paused.until.min(paused.since + Duration::hours(max as i64)), ..paused });
+        if held == Some(paused) {
+            return false;
+        }
+        self.set(held.filter(|held| held.until > now));
+        true
+    }
+
+    fn set(&mut self, paused: Option<Paused>) {
+        self.paused = paused;
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(paused.as_ref()) {
+                tracing::warn!("Failed to store the access pause: {}", e);
+            }
+        }
+    }
+}
+
+/// Where a pause is kept
+#[derive(Debug, Clone)]
+pub struct PauseStore {
+    path: PathBuf,
+}
+
+impl PauseStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// `pause.json` next to the default config
+    pub fn default_location() -> Self {
+        Self::new(ClientConfig::default_path().with_file_name("pause.json"))
+    }
+
+    fn load(&self) -> Option<Paused> {
+        let content = std::fs::read_to_string(&self.path).ok()?;
+        match serde_json::from_str(&content) {
+            Ok(paused) => Some(paused),
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable pause {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Keep `paused`, or remove the file for none
+    fn save(&self, paused: Option<&Paused>) -> Result<()> {
+        let Some(paused) = paused else {
+            return match std::fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("Failed to remove {}", self.path.display()))
+                }
+                _ => Ok(()),
+            };
+        };
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(paused)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn test_pause_is_held_to_policy() {
+        let now = at("2026-10-17T09:00:00Z");
+        let mut pause = AccessPause::default();
+        assert_eq!(pause.pause(2, None, now), Err(PauseRefused::NotAllowed));
+        assert_eq!(pause.pause(9, Some(8), now), Err(PauseRefused::TooLong(8)));
+        assert_eq!(pause.pause(0, Some(8), now), Err(PauseRefused::ZeroHours));
+        assert_eq!(pause.until(now), None);
+
+        assert_eq!(pause.pause(8, Some(8), now), Ok(at("2026-10-17T17:00:00Z")));
+        assert_eq!(pause.until(at("2026-10-17T16:59:00Z")), Some(at("2026-10-17T17:00:00Z")));
+        assert_eq!(pause.until(at("2026-10-17T17:00:00Z")), None);
+
+        // A tighter policy shortens the pause from when it started
+        let later = at("2026-10-17T10:00:00Z");
+        assert!(!pause.hold_to(Some(8), later));
+        assert!(pause.hold_to(Some(4), later));
+        assert_eq!(pause.until(later), Some(at("2026-10-17T13:00:00Z")));
+        // ...or ends it when it already ran that long
+        assert!(pause.hold_to(Some(1), later));
+        assert_eq!(pause.until(later), None);
+
+        pause.pause(2, Some(8), later).unwrap();
+        assert!(pause.hold_to(None, later));
+        assert_eq!(pause.until(later), None);
+        assert!(!pause.resume(later));
+    }
+
+    #[test]
+    fn test_pause_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PauseStore::new(dir.path().join("ghostlink").join("pause.json"));
+        let now = Utc::now();
+
+        let mut pause = AccessPause::with_store(store.clone());
+        assert_eq!(pause.until(now), None);
+        let until = pause.pause(3, Some(24), now).unwrap();
+        assert_eq!(AccessPause::with_store(store.clone()).until(now), Some(until));
+
+        assert!(pause.resume(now));
+        assert!(!store.path.exists());
+        assert_eq!(AccessPause::with_store(store.clone()).until(now), None);
+
+        std::fs::write(&store.path, "not json").unwrap();
+        assert_eq!(AccessPause::with_store(store).until(now), None);
+    }
+}
@@ -0,0 +1,168 @@
+//! Messages of the local control API
+//!
+//! Every message is one line of JSON. The tray writes a [`ControlRequest`]
+//! and reads the [`ControlResponse`] to it before sending the next; a
+//! connection may carry any number of them.
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{Context, GhostLinkError, Result};
+use crate::session::SessionType;
+
+/// Longest line either side accepts
+pub const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// What the tray asks of the agent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    /// End a session on the end user's behalf
+    Disconnect { session_id: String },
+    /// End running sessions and refuse new ones for `hours`
+    Pause { hours: u32 },
+    Resume,
+    /// Join a technician's support code with this agent
+    Join { code: String },
+}
+
+/// The agent's answer to a [`ControlRequest`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Status { status: AgentStatus },
+    Done,
+    /// The relay took the support code; the technician connects next
+    Joined { code: String, expires_at: DateTime<Utc> },
+    Error { message: String },
+}
+
+impl ControlResponse {
+    pub fn error(message: impl Into<String>) -> Self {
+        ControlResponse::Error { message: message.into() }
+    }
+}
+
+/// What the tray shows
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentStatus {
+    /// Connected to the relay
+    pub connected: bool,
+    pub hostname: String,
+    pub sessions: Vec<SessionSummary>,
+    /// Remote access is paused until then
+    #[serde(default)]
+    pub paused_until: Option<DateTime<Utc>>,
+    /// Longest pause policy allows, in hours; none when the end user may
+    /// not pause
+    #[serde(default)]
+    pub max_pause_hours: Option<u32>,
+}
+
+/// A running session as the end user sees it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub session_type: SessionType,
+    /// Who requested it
+    pub technician: Option<String>,
+    pub started_at: DateTime<Utc>,
+    /// Whether policy lets the end user end it
+    pub may_disconnect: bool,
+}
+
+/// Write `message` as one line
+pub async fn write_line<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read the next line as a `T`; `None` once the other side has closed
+pub async fn read_line<R: AsyncBufRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line).await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() > MAX_LINE_LEN {
+            return Err(GhostLinkError::Protocol(format!("message longer than {} bytes", MAX_LINE_LEN)));
+        }
+        return Err(GhostLinkError::Protocol("connection closed mid-message".to_string()));
+    }
+    serde_json::from_slice(&line).context("malformed control message").map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_messages_round_trip() {
+        let status = AgentStatus {
+            connected: true,
+            hostname: "desk-12".to_string(),
+            sessions: vec![SessionSummary {
+                session_id: "s-1".to_string(),
+                session_type: SessionType::Backstage,
+                technician: Some("tech@example.com".to_string()),
+                started_at: "2026-10-17T09:30:00Z".parse().unwrap(),
+                may_disconnect: false,
+            }],
+            paused_until: None,
+            max_pause_hours: Some(8),
+        };
+        let requests = [
+            ControlRequest::Status,
+            ControlRequest::Disconnect { session_id: "s-1".to_string() },
+            ControlRequest::Pause { hours: 2 },
+            ControlRequest::Resume,
+            ControlRequest::Join { code: "K7Q29X".to_string() },
+        ];
+        let responses = [
+            ControlResponse::Status { status },
+            ControlResponse::Done,
+            ControlResponse::Joined { code: "K7Q29X".to_string(), expires_at: "2026-10-17T09:45:00Z".parse().unwrap() },
+            ControlResponse::error("Policy does not let you end backstage sessions"),
+        ];
+
+        let mut wire = Vec::new();
+        for request in &requests {
+            write_line(&mut wire, request).await.unwrap();
+        }
+        for response in &responses {
+            write_line(&mut wire, response).await.unwrap();
+        }
+        assert_eq!(wire.iter().filter(|&&b| b == b'\n').count(), requests.len() + responses.len());
+
+        let mut reader = BufReader::new(wire.as_slice());
+        for request in &requests {
+            assert_eq!(read_line::<_, ControlRequest>(&mut reader).await.unwrap().as_ref(), Some(request));
+        }
+        for response in &responses {
+            assert_eq!(read_line::<_, ControlResponse>(&mut reader).await.unwrap().as_ref(), Some(response));
+        }
+        assert_eq!(read_line::<_, ControlRequest>(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_lines_are_rejected() {
+        let mut reader = BufReader::new(&b"{\"type\":\"reboot\"}\n"[..]);
+        assert!(read_line::<_, ControlRequest>(&mut reader).await.is_err());
+
+        let mut reader = BufReader::new(&b"{\"type\":\"status\""[..]);
+        assert!(read_line::<_, ControlRequest>(&mut reader).await.is_err());
+
+        let long = vec![b' '; MAX_LINE_LEN + 10];
+        let mut reader = BufReader::new(long.as_slice());
+        let error = read_line::<_, ControlRequest>(&mut reader).await.unwrap_err();
+        assert!(error.to_string().contains("longer than"), "{}", error);
+    }
+}
@@ -0,0 +1,230 @@
+//! The agent's end of the control API
+//!
+//! Each connection is served on its own task; its requests go to the
+//! agent's event loop one at a time. On Unix the socket checks the uid of
+//! every peer with `SO_PEERCRED`/`getpeereid`; the Windows pipe admits only
+//! SYSTEM and interactive users, and no remote clients.
+
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use crate::error::{Context, Result};
+
+use super::protocol::{read_line, write_line, ControlRequest, ControlResponse};
+use super::ControlCommand;
+
+/// First uid of accounts people log in with
+#[cfg(target_os = "macos")]
+const FIRST_LOGIN_UID: u32 = 500;
+#[cfg(not(target_os = "macos"))]
+const FIRST_LOGIN_UID: u32 = 1000;
+
+const NOBODY_UID: u32 = 65534;
+
+/// Whether a peer running as `uid` may control an agent running as
+/// `agent_uid`: root and the agent's own account may; so may anyone logged
+/// in to the machine, unless the agent runs as one of them, in which case
+/// it serves only that person
+pub fn peer_allowed(uid: u32, agent_uid: u32) -> bool {
+    let login = |uid: u32| uid >= FIRST_LOGIN_UID && uid != NOBODY_UID;
+    uid == 0 || uid == agent_uid || (!login(agent_uid) && login(uid))
+}
+
+/// Serve the control API at `path` until listening fails
+#[cfg(unix)]
+pub async fn serve(path: &Path, commands: mpsc::Sender<ControlCommand>) -> Result<()> {
+    // SAFETY: plain syscall without arguments
+    let agent_uid = unsafe { libc::geteuid() };
+    let listener = bind(path, agent_uid)?;
+    info!("Control API listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await.context("Failed to accept a control connection")?;
+        let uid = match stream.peer_cred() {
+            Ok(credentials) => credentials.uid(),
+            Err(e) => {
+                warn!("Dropping control connection without credentials: {}", e);
+                continue;
+            }
+        };
+        if !peer_allowed(uid, agent_uid) {
+            warn!("Refused control connection from uid {}", uid);
+            continue;
+        }
+        debug!("Control connection from uid {}", uid);
+        tokio::spawn(handle_connection(stream, commands.clone()));
+    }
+}
+
+/// Listen at `path`, replacing a socket an earlier run left behind. Peers
+/// are checked by uid, so the socket is open to everyone unless the agent
+/// runs as a login user.
+#[cfg(unix)]
+fn bind(path: &Path, agent_uid: u32) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed to remove stale socket {}", path.display()));
+        }
+        _ => {}
+    }
+    let listener = tokio::net::UnixListener::bind(path).with_context(|| format!("Failed to listen on {}", path.display()))?;
+    let mode = if agent_uid < FIRST_LOGIN_UID { 0o666 } else { 0o600 };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Serve the control API on pipe `name` until creating the pipe fails
+#[cfg(windows)]
+pub async fn serve(name: &Path, commands: mpsc::Sender<ControlCommand>) -> Result<()> {
+    let mut pipe = create_pipe(name, true)?;
+    info!("Control API listening on {}", name.display());
+
+    loop {
+        pipe.connect().await.context("Failed to accept a control connection")?;
+        // The next client connects to a fresh instance
+        let connected = std::mem::replace(&mut pipe, create_pipe(name, false)?);
+        tokio::spawn(handle_connection(connected, commands.clone()));
+    }
+}
+
+#[cfg(windows)]
+fn create_pipe(name: &Path, first: bool) -> Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+    use windows::core::w;
+    use crate::error::GhostLinkError;
+    use windows::Win32::Foundation::{LocalFree, BOOL, HLOCAL};
+    use windows::Win32::Security::Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+    use windows::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+
+    // SYSTEM gets full control; interactive users may read and write
+    unsafe {
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(w!("D:P(A;;GA;;;SY)(A;;GRGW;;;IU)"), SDDL_REVISION_1, &mut descriptor, None)
+            .map_err(|e| GhostLinkError::Other(format!("ConvertStringSecurityDescriptorToSecurityDescriptorW failed: {}", e)))?;
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.0,
+            bInheritHandle: BOOL::from(false),
+        };
+
+        let pipe = ServerOptions::new()
+            .first_pipe_instance(first)
+            .reject_remote_clients(true)
+            .create_with_security_attributes_raw(name, &mut attributes as *mut SECURITY_ATTRIBUTES as *mut _);
+        let _ = LocalFree(HLOCAL(descriptor.0));
+        pipe.with_context(|| format!("Failed to create pipe {}", name.display()))
+    }
+}
+
+/// Answer the requests of one connection until it closes or sends
+/// something malformed
+async fn handle_connection<S: AsyncRead + AsyncWrite>(stream: S, commands: mpsc::Sender<ControlCommand>) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    loop {
+        let response = match read_line::<_, ControlRequest>(&mut reader).await {
+            Ok(Some(request)) => dispatch(&commands, request).await,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Closing control connection: {}", e);
+                let _ = write_line(&mut writer, &ControlResponse::error(e.to_string())).await;
+                break;
+            }
+        };
+        if write_line(&mut writer, &response).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Hand `request` to the agent and wait for its answer
+async fn dispatch(commands: &mpsc::Sender<ControlCommand>, request: ControlRequest) -> ControlResponse {
+    let (reply, answer) = oneshot::channel();
+    if commands.send(ControlCommand { request, reply }).await.is_err() {
+        return ControlResponse::error("The agent is shutting down");
+    }
+    answer.await.unwrap_or_else(|_| ControlResponse::error("The agent dropped the request"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_allowed() {
+        // An agent running as root or a service account serves everyone logged in
+        for agent_uid in [0, 110] {
+            assert!(peer_allowed(0, agent_uid));
+            assert!(peer_allowed(agent_uid, agent_uid));
+            assert!(peer_allowed(FIRST_LOGIN_UID, agent_uid));
+            assert!(peer_allowed(FIRST_LOGIN_UID + 500, agent_uid));
+            assert!(!peer_allowed(NOBODY_UID, agent_uid));
+            assert!(!peer_allowed(33, agent_uid));
+        }
+        // One running as a login user only serves them
+        let agent_uid = FIRST_LOGIN_UID + 1;
+        assert!(peer_allowed(agent_uid, agent_uid));
+        assert!(peer_allowed(0, agent_uid));
+        assert!(!peer_allowed(FIRST_LOGIN_UID, agent_uid));
+        assert!(!peer_allowed(110, agent_uid));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_requests_reach_the_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ghostlink").join("control.sock");
+        // A stale socket from an earlier run is replaced
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "").unwrap();
+
+        let (commands, mut agent) = mpsc::channel(4);
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, commands).await }
+        });
+        // Stands in for the agent's event loop
+        tokio::spawn(async move {
+            while let Some(ControlCommand { request, reply }) = agent.recv().await {
+                let response = match request {
+                    ControlRequest::Pause { hours } if hours > 8 => ControlResponse::error("too long"),
+                    ControlRequest::Pause { .. } => ControlResponse::Done,
+                    other => ControlResponse::error(format!("unexpected {:?}", other)),
+                };
+                let _ = reply.send(response);
+            }
+        });
+
+        let stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        // Several requests share a connection
+        write_line(&mut writer, &ControlRequest::Pause { hours: 2 }).await.unwrap();
+        assert_eq!(read_line(&mut reader).await.unwrap(), Some(ControlResponse::Done));
+        write_line(&mut writer, &ControlRequest::Pause { hours: 12 }).await.unwrap();
+        assert_eq!(read_line(&mut reader).await.unwrap(), Some(ControlResponse::error("too long")));
+
+        // Garbage gets an error and the connection closed
+        tokio::io::AsyncWriteExt::write_all(&mut writer, b"shutdown now\n").await.unwrap();
+        let response: ControlResponse = read_line(&mut reader).await.unwrap().unwrap();
+        assert!(matches!(response, ControlResponse::Error { .. }));
+        assert_eq!(read_line::<_, ControlResponse>(&mut reader).await.unwrap(), None);
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert!(mode == 0o666 || mode == 0o600, "{:o}", mode);
+        server.abort();
+    }
+}
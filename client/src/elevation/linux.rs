@@ -0,0 +1,125 @@
+//! Elevation on Linux through sudo rules or polkit
+//!
+//! Nobody is at the agent's terminal to type a password, so only rules that
+//! let it through without one count: a `NOPASSWD` sudoers entry, or a polkit
+//! rule returning `polkit.Result.YES` for `org.freedesktop.policykit.exec`.
+//! Each is asked first, so a refusal is reported as denied instead of
+//! surfacing later as a hung or failed command.
+
+use std::io::ErrorKind;
+use std::process::{Command, Stdio};
+
+use super::sudo::{self, Probe};
+use super::{ElevationBackend, ElevationLevel, ElevationMethod};
+use crate::error::ElevationError;
+
+/// polkit action that pkexec checks
+const PKEXEC_ACTION: &str = "org.freedesktop.policykit.exec";
+
+pub struct LinuxElevation;
+
+impl ElevationBackend for LinuxElevation {
+    fn elevate(&self, level: &ElevationLevel) -> Result<ElevationMethod, ElevationError> {
+        let user = level.target_user();
+        select_method(user, sudo::is_root(), || sudo::probe(user), probe_polkit)
+    }
+}
+
+/// Pick the first way in that is allowed; sudo rules are explicit
+/// configuration, so they are preferred over polkit
+fn select_method(
+    user: Option<&str>,
+    root: bool,
+    sudo: impl FnOnce() -> Probe,
+    polkit: impl FnOnce() -> Probe,
+) -> Result<ElevationMethod, ElevationError> {
+    if root && user.is_none() {
+        return Ok(ElevationMethod::Inherited);
+    }
+    let user = user.map(str::to_string);
+
+    let sudo = sudo();
+    if sudo == Probe::Allowed {
+        return Ok(ElevationMethod::Sudo { user });
+    }
+    let polkit = polkit();
+    if polkit == Probe::Allowed {
+        return Ok(ElevationMethod::Pkexec { user });
+    }
+
+    match (sudo, polkit) {
+        (Probe::Missing, Probe::Missing) => Err(ElevationError::Unavailable {
+            reason: "neither sudo nor polkit is installed".to_string(),
+        }),
+        (sudo, polkit) => Err(ElevationError::Denied {
+            reason: format!("sudo: {}; polkit: {}", sudo, polkit),
+        }),
+    }
+}
+
+/// Ask polkit whether the agent may use pkexec without authenticating
+fn probe_polkit() -> Probe {
+    let output = Command::new("pkcheck")
+        .args(["--action-id", PKEXEC_ACTION, "--process", &std::process::id().to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output();
+
+    match output {
+        Ok(output) => match output.status.code() {
+            Some(0) => Probe::Allowed,
+            Some(1) => Probe::Refused("not authorized by polkit rules".to_string()),
+            Some(2) => Probe::Refused("polkit requires interactive authentication".to_string()),
+            _ => Probe::Refused(sudo::first_line(&output.stderr, "pkcheck failed")),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => Probe::Missing,
+        Err(e) => Probe::Refused(format!("pkcheck could not be run: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refused(reason: &str) -> impl FnOnce() -> Probe + '_ {
+        move || Probe::Refused(reason.to_string())
+    }
+
+    #[test]
+    fn test_root_inherits_unless_switching_user() {
+        let unused = || -> Probe { panic!("no probe needed as root") };
+        assert_eq!(select_method(None, true, unused, unused).unwrap(), ElevationMethod::Inherited);
+
+        let method = select_method(Some("postgres"), true, || Probe::Allowed, refused("unused")).unwrap();
+        assert_eq!(method, ElevationMethod::Sudo { user: Some("postgres".to_string()) });
+    }
+
+    #[test]
+    fn test_sudo_preferred_then_polkit() {
+        let method = select_method(None, false, || Probe::Allowed, || Probe::Allowed).unwrap();
+        assert_eq!(method, ElevationMethod::Sudo { user: None });
+
+        let method = select_method(None, false, || Probe::Missing, || Probe::Allowed).unwrap();
+        assert_eq!(method, ElevationMethod::Pkexec { user: None });
+    }
+
+    #[test]
+    fn test_refusals_are_denials() {
+        let err = select_method(
+            None,
+            false,
+            refused("a password is required"),
+            refused("not authorized by polkit rules"),
+        ).unwrap_err();
+        match err {
+            ElevationError::Denied { reason } => {
+                assert!(reason.contains("sudo: a password is required"));
+                assert!(reason.contains("polkit: not authorized"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let err = select_method(None, false, || Probe::Missing, || Probe::Missing).unwrap_err();
+        assert!(matches!(err, ElevationError::Unavailable { .. }));
+    }
+}
@@ -0,0 +1,45 @@
+//! Elevation on macOS through the privileged helper
+//!
+//! `AuthorizationExecuteWithPrivileges` is deprecated and needs someone at
+//! the machine to authenticate. Instead the installer registers a helper
+//! with launchd (SMJobBless-style) into `/Library/PrivilegedHelperTools`,
+//! and elevated commands go through it. Without the helper, `NOPASSWD` sudo
+//! rules are used if present.
+
+use std::path::Path;
+
+use super::sudo::{self, Probe};
+use super::{ElevationBackend, ElevationLevel, ElevationMethod};
+use crate::error::ElevationError;
+
+/// Where the installer places the privileged helper
+pub const HELPER_PATH: &str = "/Library/PrivilegedHelperTools/com.ghostlink.agent.helper";
+
+pub struct MacElevation;
+
+impl ElevationBackend for MacElevation {
+    fn elevate(&self, level: &ElevationLevel) -> Result<ElevationMethod, ElevationError> {
+        let user = level.target_user();
+        if sudo::is_root() && user.is_none() {
+            return Ok(ElevationMethod::Inherited);
+        }
+
+        let helper = Path::new(HELPER_PATH);
+        if helper.exists() {
+            return Ok(ElevationMethod::PrivilegedHelper {
+                path: helper.to_path_buf(),
+                user: user.map(str::to_string),
+            });
+        }
+
+        match sudo::probe(user) {
+            Probe::Allowed => Ok(ElevationMethod::Sudo { user: user.map(str::to_string) }),
+            Probe::Missing => Err(ElevationError::Unavailable {
+                reason: format!("the privileged helper is not installed at {}; reinstall the agent package", HELPER_PATH),
+            }),
+            Probe::Refused(reason) => Err(ElevationError::Denied {
+                reason: format!("the privileged helper is not installed and sudo refused: {}", reason),
+            }),
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! Privilege elevation for sessions
+//!
+//! The server's PAM flow decides whether a session may elevate; once an
+//! elevation is approved and started it sends the agent an
+//! `ElevationRequest`. The agent obtains the rights through the platform —
+//! polkit or sudo rules on Linux, the privileged helper on macOS, UAC or the
+//! console session token on Windows — and reports the outcome back. While a
+//! session is elevated, commands it runs are wrapped to use those rights.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::connection::RelayMessage;
+use crate::error::ElevationError;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(unix)]
+pub mod sudo;
+#[cfg(windows)]
+mod windows;
+
+/// Rights requested by the server, mirroring its PAM `ElevationType`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ElevationLevel {
+    RunAsAdmin,
+    RunAsUser(String),
+    RunAsService(String),
+    RunAsSystem,
+    DomainAdmin,
+    LocalAdmin,
+}
+
+impl ElevationLevel {
+    /// Account commands should run as, or `None` for the superuser
+    pub fn target_user(&self) -> Option<&str> {
+        match self {
+            ElevationLevel::RunAsUser(user) | ElevationLevel::RunAsService(user) => Some(user),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ElevationLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElevationLevel::RunAsAdmin => write!(f, "administrator"),
+            ElevationLevel::RunAsUser(user) => write!(f, "user {}", user),
+            ElevationLevel::RunAsService(account) => write!(f, "service account {}", account),
+            ElevationLevel::RunAsSystem => write!(f, "SYSTEM"),
+            ElevationLevel::DomainAdmin => write!(f, "domain administrator"),
+            ElevationLevel::LocalAdmin => write!(f, "local administrator"),
+        }
+    }
+}
+
+/// How elevated commands get their rights
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum ElevationMethod {
+    /// The agent already holds the rights (root, SYSTEM or an elevated token)
+    Inherited,
+    /// polkit's pkexec, authorized by a polkit rule
+    Pkexec { user: Option<String> },
+    /// sudo, with rules that need no password
+    Sudo { user: Option<String> },
+    /// The privileged helper installed alongside the agent on macOS
+    PrivilegedHelper { path: PathBuf, user: Option<String> },
+    /// The session helper was started in the console user's elevated token
+    ConsoleSession { pid: u32 },
+    /// The session helper was relaunched through UAC
+    Relaunched { pid: u32 },
+}
+
+impl std::fmt::Display for ElevationMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElevationMethod::Inherited => write!(f, "agent privileges"),
+            ElevationMethod::Pkexec { .. } => write!(f, "polkit"),
+            ElevationMethod::Sudo { .. } => write!(f, "sudo"),
+            ElevationMethod::PrivilegedHelper { path, .. } => write!(f, "privileged helper {}", path.display()),
+            ElevationMethod::ConsoleSession { pid } => write!(f, "console session helper (pid {})", pid),
+            ElevationMethod::Relaunched { pid } => write!(f, "elevated session helper (pid {})", pid),
+        }
+    }
+}
+
+/// Rights a session currently holds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ElevatedContext {
+    pub level: ElevationLevel,
+    pub method: ElevationMethod,
+    pub granted_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ElevatedContext {
+    pub fn new(level: ElevationLevel, method: ElevationMethod) -> Self {
+        Self {
+            level,
+            method,
+            granted_at: chrono::Utc::now(),
+        }
+    }
+
+    /// Program and arguments that run `program` with these rights
+    pub fn wrap(&self, program: &str, args: &[String]) -> Result<(String, Vec<String>), ElevationError> {
+        let mut wrapped = Vec::new();
+        let launcher = match &self.method {
+            ElevationMethod::Inherited => return Ok((program.to_string(), args.to_vec())),
+            ElevationMethod::Pkexec { user } => {
+                if let Some(user) = user {
+                    wrapped.extend(["--user".to_string(), user.clone()]);
+                }
+                "pkexec".to_string()
+            }
+            ElevationMethod::Sudo { user } => {
+                wrapped.push("-n".to_string());
+                if let Some(user) = user {
+                    wrapped.extend(["-u".to_string(), user.clone()]);
+                }
+                wrapped.push("--".to_string());
+                "sudo".to_string()
+            }
+            ElevationMethod::PrivilegedHelper { path, user } => {
+                wrapped.push("exec".to_string());
+                if let Some(user) = user {
+                    wrapped.extend(["--user".to_string(), user.clone()]);
+                }
+                wrapped.push("--".to_string());
+                path.display().to_string()
+            }
+            ElevationMethod::ConsoleSession { .. } | ElevationMethod::Relaunched { .. } => {
+                return Err(ElevationError::Unavailable {
+                    reason: format!("elevated commands run in the {}, not in the agent", self.method),
+                });
+            }
+        };
+
+        wrapped.push(program.to_string());
+        wrapped.extend_from_slice(args);
+        Ok((launcher, wrapped))
+    }
+}
+
+/// Obtains elevated rights from the operating system, stubbed in tests
+pub trait ElevationBackend: Send + Sync {
+    /// Obtain the rights for `level`, returning how commands use them.
+    ///
+    /// May block on external tools, so call it off the async runtime.
+    fn elevate(&self, level: &ElevationLevel) -> Result<ElevationMethod, ElevationError>;
+}
+
+/// Elevation for the platform the agent runs on, if supported
+pub fn platform_backend() -> Option<Arc<dyn ElevationBackend>> {
+    #[cfg(target_os = "linux")]
+    {
+        Some(Arc::new(linux::LinuxElevation))
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        Some(Arc::new(macos::MacElevation))
+    }
+
+    #[cfg(windows)]
+    {
+        Some(Arc::new(windows::WindowsElevation))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    {
+        None
+    }
+}
+
+/// A session's elevation, shared by everything that runs commands for it
+#[derive(Clone, Default)]
+pub struct SessionElevation {
+    context: Arc<RwLock<Option<ElevatedContext>>>,
+}
+
+impl SessionElevation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Elevate to `level`. A repeated request for the active level reuses it;
+    /// a failed request leaves any earlier elevation in place.
+    pub async fn elevate(
+        &self,
+        backend: Arc<dyn ElevationBackend>,
+        level: ElevationLevel,
+    ) -> Result<ElevatedContext, ElevationError> {
+        if let Some(context) = self.context.read().await.as_ref().filter(|c| c.level == level) {
+            return Ok(context.clone());
+        }
+
+        let requested = level.clone();
+        let method = tokio::task::spawn_blocking(move || backend.elevate(&requested))
+            .await
+            .map_err(|e| ElevationError::Failed { reason: format!("elevation task failed: {}", e) })??;
+
+        let context = ElevatedContext::new(level, method);
+        *self.context.write().await = Some(context.clone());
+        Ok(context)
+    }
+
+    /// The active elevation, if any
+    pub async fn current(&self) -> Option<ElevatedContext> {
+        self.context.read().await.clone()
+    }
+
+    /// Drop the elevation; later commands run with the agent's own rights
+    pub async fn revoke(&self) -> Option<ElevatedContext> {
+        self.context.write().await.take()
+    }
+
+    /// Program and arguments for `program`, elevated when elevation is active
+    pub async fn wrap(&self, program: &str, args: &[String]) -> Result<(String, Vec<String>), ElevationError> {
+        match self.context.read().await.as_ref() {
+            Some(context) => context.wrap(program, args),
+            None => Ok((program.to_string(), args.to_vec())),
+        }
+    }
+}
+
+/// Timeline description of an elevation attempt
+pub fn describe(level: &ElevationLevel, result: &Result<ElevatedContext, ElevationError>) -> String {
+    match result {
+        Ok(context) => format!("Elevated to {} via {}", level, context.method),
+        Err(ElevationError::Denied { reason }) => format!("Elevation to {} denied: {}", level, reason),
+        Err(e) => format!("Elevation to {} failed: {}", level, e),
+    }
+}
+
+/// Report an elevation attempt to the server
+pub fn result_message(
+    session_id: &str,
+    level: &ElevationLevel,
+    result: &Result<ElevatedContext, ElevationError>,
+) -> RelayMessage {
+    match result {
+        Ok(context) => {
+            info!("Session {} elevated to {} via {}", session_id, level, context.method);
+            RelayMessage::ElevationResult {
+                session_id: session_id.to_string(),
+                level: level.clone(),
+                success: true,
+                denied: false,
+                method: Some(context.method.clone()),
+                error: None,
+            }
+        }
+        Err(e) => {
+            warn!("Elevation to {} for session {} failed: {}", level, session_id, e);
+            RelayMessage::ElevationResult {
+                session_id: session_id.to_string(),
+                level: level.clone(),
+                success: false,
+                denied: matches!(e, ElevationError::Denied { .. }),
+                method: None,
+                error: Some(e.to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Grants everything through sudo, or denies everything
+    #[derive(Default)]
+    struct StubBackend {
+        deny: bool,
+        calls: AtomicUsize,
+    }
+
+    impl ElevationBackend for StubBackend {
+        fn elevate(&self, level: &ElevationLevel) -> Result<ElevationMethod, ElevationError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.deny {
+                return Err(ElevationError::Denied { reason: "no sudo rule allows this".to_string() });
+            }
+            Ok(ElevationMethod::Sudo { user: level.target_user().map(str::to_string) })
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_elevation_wraps_commands() {
+        let elevation = SessionElevation::new();
+        let plain = elevation.wrap("ls", &args(&["-l"])).await.unwrap();
+        assert_eq!(plain, ("ls".to_string(), args(&["-l"])));
+
+        let backend = Arc::new(StubBackend::default());
+        let context = elevation.elevate(backend.clone(), ElevationLevel::RunAsAdmin).await.unwrap();
+        assert_eq!(context.method, ElevationMethod::Sudo { user: None });
+        let wrapped = elevation.wrap("ls", &args(&["-l"])).await.unwrap();
+        assert_eq!(wrapped, ("sudo".to_string(), args(&["-n", "--", "ls", "-l"])));
+
+        // Asking again for the active level does not go back to the platform
+        elevation.elevate(backend.clone(), ElevationLevel::RunAsAdmin).await.unwrap();
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 1);
+
+        elevation.elevate(backend, ElevationLevel::RunAsUser("svc".to_string())).await.unwrap();
+        let wrapped = elevation.wrap("id", &[]).await.unwrap();
+        assert_eq!(wrapped, ("sudo".to_string(), args(&["-n", "-u", "svc", "--", "id"])));
+
+        elevation.revoke().await;
+        assert_eq!(elevation.wrap("id", &[]).await.unwrap(), ("id".to_string(), Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_denied_elevation_is_reported() {
+        let elevation = SessionElevation::new();
+        let backend = Arc::new(StubBackend { deny: true, ..Default::default() });
+        let level = ElevationLevel::RunAsSystem;
+
+        let result = elevation.elevate(backend, level.clone()).await;
+        assert!(matches!(result, Err(ElevationError::Denied { .. })));
+        assert!(elevation.current().await.is_none());
+        assert!(describe(&level, &result).starts_with("Elevation to SYSTEM denied"));
+
+        match result_message("session-1", &level, &result) {
+            RelayMessage::ElevationResult { session_id, success, denied, method, error, .. } => {
+                assert_eq!(session_id, "session-1");
+                assert!(!success);
+                assert!(denied);
+                assert!(method.is_none());
+                assert!(error.unwrap().contains("no sudo rule"));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_elevation_keeps_earlier_rights() {
+        let elevation = SessionElevation::new();
+        elevation.elevate(Arc::new(StubBackend::default()), ElevationLevel::RunAsAdmin).await.unwrap();
+
+        let denying = Arc::new(StubBackend { deny: true, ..Default::default() });
+        assert!(elevation.elevate(denying, ElevationLevel::RunAsSystem).await.is_err());
+        assert_eq!(elevation.current().await.unwrap().level, ElevationLevel::RunAsAdmin);
+    }
+
+    #[test]
+    fn test_helper_methods_do_not_wrap_agent_commands() {
+        let context = ElevatedContext::new(ElevationLevel::RunAsAdmin, ElevationMethod::Relaunched { pid: 42 });
+        let err = context.wrap("whoami", &[]).unwrap_err();
+        assert!(matches!(err, ElevationError::Unavailable { .. }));
+        assert!(err.to_string().contains("pid 42"));
+
+        let helper = ElevatedContext::new(
+            ElevationLevel::RunAsUser("admin".to_string()),
+            ElevationMethod::PrivilegedHelper { path: PathBuf::from("/Library/PrivilegedHelperTools/helper"), user: Some("admin".to_string()) },
+        );
+        let (program, wrapped) = helper.wrap("whoami", &[]).unwrap();
+        assert_eq!(program, "/Library/PrivilegedHelperTools/helper");
+        assert_eq!(wrapped, args(&["exec", "--user", "admin", "--", "whoami"]));
+    }
+
+    #[test]
+    fn test_message_wire_format() {
+        // As sent by the server's PAM flow
+        let request: RelayMessage = serde_json::from_str(
+            r#"{"type":"ElevationRequest","session_id":"s1","level":{"RunAsUser":"bob"}}"#,
+        ).unwrap();
+        let RelayMessage::ElevationRequest { session_id, level } = request else {
+            panic!("not an elevation request");
+        };
+        assert_eq!(session_id, "s1");
+        assert_eq!(level, ElevationLevel::RunAsUser("bob".to_string()));
+
+        let context = ElevatedContext::new(level.clone(), ElevationMethod::Pkexec { user: Some("bob".to_string()) });
+        let json = serde_json::to_value(result_message("s1", &level, &Ok(context))).unwrap();
+        assert_eq!(json["type"], "ElevationResult");
+        assert_eq!(json["success"], true);
+        assert_eq!(json["level"]["RunAsUser"], "bob");
+        assert_eq!(json["method"]["method"], "pkexec");
+        assert_eq!(json["method"]["user"], "bob");
+    }
+}
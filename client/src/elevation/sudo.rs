@@ -0,0 +1,56 @@
+//! sudo rules, shared by the Linux and macOS backends
+
+use std::io::ErrorKind;
+use std::process::{Command, Stdio};
+
+/// What an authorization tool said when asked, without prompting, whether
+/// it would let the agent through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    Allowed,
+    Refused(String),
+    Missing,
+}
+
+impl std::fmt::Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Allowed => write!(f, "allowed"),
+            Probe::Refused(reason) => write!(f, "{}", reason),
+            Probe::Missing => write!(f, "not installed"),
+        }
+    }
+}
+
+/// Whether the agent runs as root
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Ask sudo whether it would run commands as `user` (root if `None`)
+/// without a password
+pub fn probe(user: Option<&str>) -> Probe {
+    let mut command = Command::new("sudo");
+    command.arg("-n");
+    if let Some(user) = user {
+        command.args(["-u", user]);
+    }
+    command.arg("true").stdin(Stdio::null()).stdout(Stdio::null());
+
+    match command.output() {
+        Ok(output) if output.status.success() => Probe::Allowed,
+        Ok(output) => Probe::Refused(first_line(&output.stderr, "sudo refused without a password")),
+        Err(e) if e.kind() == ErrorKind::NotFound => Probe::Missing,
+        Err(e) => Probe::Refused(format!("sudo could not be run: {}", e)),
+    }
+}
+
+/// First line of a tool's error output, or `fallback` if it printed nothing
+pub fn first_line(stderr: &[u8], fallback: &str) -> String {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or(fallback)
+        .to_string()
+}
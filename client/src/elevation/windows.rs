@@ -0,0 +1,276 @@
+//! Elevation on Windows
+//!
+//! The agent service runs as LocalSystem, which already outranks every
+//! administrator level, so commands simply inherit its token. Running as a
+//! particular user means starting the session helper in the console session
+//! with that user's elevated (linked) token. An agent started interactively
+//! without elevation relaunches its session helper through UAC ("runas"),
+//! which the user at the console has to accept.
+
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt;
+
+use windows::core::{w, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{CloseHandle, BOOL, ERROR_CANCELLED, HANDLE};
+use windows::Win32::Security::{
+    GetTokenInformation, IsWellKnownSid, TokenElevation, TokenLinkedToken, TokenUser, WinLocalSystemSid,
+    TOKEN_ELEVATION, TOKEN_LINKED_TOKEN, TOKEN_QUERY, TOKEN_USER,
+};
+use windows::Win32::System::RemoteDesktop::{
+    WTSFreeMemory, WTSGetActiveConsoleSessionId, WTSQuerySessionInformationW, WTSQueryUserToken, WTSUserName,
+    WTS_CURRENT_SERVER_HANDLE,
+};
+use windows::Win32::System::Threading::{
+    CreateProcessAsUserW, GetCurrentProcess, GetProcessId, OpenProcessToken, PROCESS_CREATION_FLAGS,
+    PROCESS_INFORMATION, STARTUPINFOW,
+};
+use windows::Win32::UI::Shell::{ShellExecuteExW, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW};
+use windows::Win32::UI::WindowsAndMessaging::SW_SHOWNORMAL;
+
+use super::{ElevationBackend, ElevationLevel, ElevationMethod};
+use crate::error::ElevationError;
+
+pub struct WindowsElevation;
+
+impl ElevationBackend for WindowsElevation {
+    fn elevate(&self, level: &ElevationLevel) -> Result<ElevationMethod, ElevationError> {
+        let token = current_token()?;
+
+        match level {
+            ElevationLevel::RunAsUser(user) => {
+                if !token.is_system {
+                    return Err(ElevationError::Unavailable {
+                        reason: "running as another user needs the agent service, which runs as LocalSystem".to_string(),
+                    });
+                }
+                let pid = spawn_in_console_session(user)?;
+                Ok(ElevationMethod::ConsoleSession { pid })
+            }
+            ElevationLevel::RunAsService(account) => Err(ElevationError::Unavailable {
+                reason: format!("running as service account {} is not supported on Windows agents", account),
+            }),
+            _ if token.is_system || token.is_elevated => Ok(ElevationMethod::Inherited),
+            _ => Ok(ElevationMethod::Relaunched { pid: relaunch_elevated()? }),
+        }
+    }
+}
+
+struct TokenInfo {
+    is_system: bool,
+    is_elevated: bool,
+}
+
+fn failed(call: &'static str) -> impl Fn(windows::core::Error) -> ElevationError {
+    move |e| ElevationError::Failed {
+        reason: format!("{} failed: {}", call, e),
+    }
+}
+
+/// Whether the agent's own token is LocalSystem or elevated
+fn current_token() -> Result<TokenInfo, ElevationError> {
+    unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token).map_err(failed("OpenProcessToken"))?;
+        let info = token_info(token);
+        let _ = CloseHandle(token);
+        info
+    }
+}
+
+unsafe fn token_info(token: HANDLE) -> Result<TokenInfo, ElevationError> {
+    let mut elevation = TOKEN_ELEVATION::default();
+    let mut len = 0u32;
+    GetTokenInformation(
+        token,
+        TokenElevation,
+        Some(&mut elevation as *mut TOKEN_ELEVATION as *mut _),
+        std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+        &mut len,
+    )
+    .map_err(failed("GetTokenInformation"))?;
+
+    // TOKEN_USER is followed by the SID it points to; the first call sizes it
+    let _ = GetTokenInformation(token, TokenUser, None, 0, &mut len);
+    let mut buffer = vec![0u64; (len as usize).div_ceil(8)];
+    GetTokenInformation(token, TokenUser, Some(buffer.as_mut_ptr() as *mut _), len, &mut len)
+        .map_err(failed("GetTokenInformation"))?;
+    let user = &*(buffer.as_ptr() as *const TOKEN_USER);
+
+    Ok(TokenInfo {
+        is_system: IsWellKnownSid(user.User.Sid, WinLocalSystemSid).as_bool(),
+        is_elevated: elevation.TokenIsElevated != 0,
+    })
+}
+
+/// Start the session helper as the console user, with their elevated token
+/// if they are an administrator
+fn spawn_in_console_session(user: &str) -> Result<u32, ElevationError> {
+    unsafe {
+        let session = WTSGetActiveConsoleSessionId();
+        if session == u32::MAX {
+            return Err(ElevationError::Unavailable {
+                reason: "no session is attached to the console".to_string(),
+            });
+        }
+        let console_user = console_user_name(session)?;
+        if !console_user.eq_ignore_ascii_case(user) {
+            return Err(ElevationError::Denied {
+                reason: format!("{} is not the user logged on at the console ({})", user, console_user),
+            });
+        }
+
+        let mut user_token = HANDLE::default();
+        WTSQueryUserToken(session, &mut user_token).map_err(failed("WTSQueryUserToken"))?;
+        // Administrators get a filtered token at logon; the linked one is elevated
+        let token = linked_token(user_token).unwrap_or(user_token);
+
+        let mut command_line = wide(helper_command_line()?);
+        let startup = STARTUPINFOW {
+            cb: std::mem::size_of::<STARTUPINFOW>() as u32,
+            lpDesktop: PWSTR(w!("winsta0\\default").as_ptr() as *mut u16),
+            ..Default::default()
+        };
+        let mut process = PROCESS_INFORMATION::default();
+        let result = CreateProcessAsUserW(
+            token,
+            PCWSTR::null(),
+            PWSTR(command_line.as_mut_ptr()),
+            None,
+            None,
+            BOOL::from(false),
+            PROCESS_CREATION_FLAGS(0),
+            None,
+            PCWSTR::null(),
+            &startup,
+            &mut process,
+        );
+        if token != user_token {
+            let _ = CloseHandle(token);
+        }
+        let _ = CloseHandle(user_token);
+        result.map_err(failed("CreateProcessAsUserW"))?;
+
+        let _ = CloseHandle(process.hThread);
+        let _ = CloseHandle(process.hProcess);
+        Ok(process.dwProcessId)
+    }
+}
+
+unsafe fn console_user_name(session: u32) -> Result<String, ElevationError> {
+    let mut buffer = PWSTR::null();
+    let mut len = 0u32;
+    WTSQuerySessionInformationW(WTS_CURRENT_SERVER_HANDLE, session, WTSUserName, &mut buffer, &mut len)
+        .map_err(failed("WTSQuerySessionInformationW"))?;
+    let name = buffer.to_string().unwrap_or_default();
+    WTSFreeMemory(buffer.as_ptr() as *mut _);
+
+    if name.is_empty() {
+        Err(ElevationError::Unavailable {
+            reason: "no user is logged on at the console".to_string(),
+        })
+    } else {
+        Ok(name)
+    }
+}
+
+unsafe fn linked_token(token: HANDLE) -> Option<HANDLE> {
+    let mut linked = TOKEN_LINKED_TOKEN::default();
+    let mut len = 0u32;
+    GetTokenInformation(
+        token,
+        TokenLinkedToken,
+        Some(&mut linked as *mut TOKEN_LINKED_TOKEN as *mut _),
+        std::mem::size_of::<TOKEN_LINKED_TOKEN>() as u32,
+        &mut len,
+    )
+    .ok()?;
+    Some(linked.LinkedToken)
+}
+
+/// Relaunch the session helper through UAC; declining the prompt is a denial
+fn relaunch_elevated() -> Result<u32, ElevationError> {
+    let exe = std::env::current_exe().map_err(|e| ElevationError::Failed {
+        reason: format!("cannot locate the agent executable: {}", e),
+    })?;
+    let file = wide(exe.as_os_str());
+    let params = wide(join_args(std::env::args().skip(1)));
+
+    let mut info = SHELLEXECUTEINFOW {
+        cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
+        fMask: SEE_MASK_NOCLOSEPROCESS,
+        lpVerb: w!("runas"),
+        lpFile: PCWSTR(file.as_ptr()),
+        lpParameters: PCWSTR(params.as_ptr()),
+        nShow: SW_SHOWNORMAL.0,
+        ..Default::default()
+    };
+
+    unsafe {
+        if let Err(e) = ShellExecuteExW(&mut info) {
+            if e.code() == ERROR_CANCELLED.to_hresult() {
+                return Err(ElevationError::Denied {
+                    reason: "the UAC prompt was declined".to_string(),
+                });
+            }
+            return Err(failed("ShellExecuteExW")(e));
+        }
+        let pid = GetProcessId(info.hProcess);
+        let _ = CloseHandle(info.hProcess);
+        Ok(pid)
+    }
+}
+
+/// Command line that starts another copy of this session helper
+fn helper_command_line() -> Result<String, ElevationError> {
+    let exe = std::env::current_exe().map_err(|e| ElevationError::Failed {
+        reason: format!("cannot locate the agent executable: {}", e),
+    })?;
+    let exe = exe.to_string_lossy().into_owned();
+    Ok(join_args(std::iter::once(exe).chain(std::env::args().skip(1))))
+}
+
+fn join_args(args: impl Iterator<Item = String>) -> String {
+    args.map(|arg| quote_arg(&arg)).collect::<Vec<_>>().join(" ")
+}
+
+/// Quote an argument so `CommandLineToArgvW` reads it back unchanged
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            continue;
+        }
+        // Backslashes are only special in front of a quote
+        let escaped = if c == '"' { backslashes * 2 + 1 } else { backslashes };
+        quoted.extend(std::iter::repeat('\\').take(escaped));
+        quoted.push(c);
+        backslashes = 0;
+    }
+    quoted.extend(std::iter::repeat('\\').take(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+fn wide(s: impl AsRef<OsStr>) -> Vec<u16> {
+    s.as_ref().encode_wide().chain(std::iter::once(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_arg() {
+        assert_eq!(quote_arg("session"), "session");
+        assert_eq!(quote_arg(""), "\"\"");
+        assert_eq!(quote_arg("C:\\Program Files\\GhostLink"), "\"C:\\Program Files\\GhostLink\"");
+        assert_eq!(quote_arg("C:\\dir with space\\"), "\"C:\\dir with space\\\\\"");
+        assert_eq!(quote_arg("say \"hi\""), "\"say \\\"hi\\\"\"");
+    }
+}
@@ -1,7 +1,11 @@
 #![allow(dead_code)]
 
+use std::fmt;
 use thiserror::Error;
 
+use crate::session::preflight::FailureCode;
+use crate::session::SessionReadiness;
+
 /// Main error type for GhostLink client
 #[derive(Error, Debug)]
 pub enum GhostLinkError {
@@ -23,6 +27,18 @@ pub enum GhostLinkError {
     #[error("Configuration error: {0}")]
     Config(#[from] ConfigError),
     
+    #[error("Elevation error: {0}")]
+    Elevation(#[from] ElevationError),
+    
+    #[error("Toolbox error: {0}")]
+    Toolbox(#[from] ToolError),
+    
+    #[error("File transfer error: {0}")]
+    FileTransfer(#[from] FileTransferError),
+    
+    #[error("Session pre-flight failed: {0}")]
+    Preflight(#[from] PreflightError),
+    
     #[error("Encoding error: {0}")]
     Encode(String),
     
@@ -32,8 +48,12 @@ pub enum GhostLinkError {
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
     
+    /// Boxed, as it is several times the size of every other variant
     #[error("WebSocket error: {0}")]
-    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    WebSocket(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+    
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
     
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
@@ -92,6 +112,46 @@ pub enum CaptureError {
     
     #[error("System dependency missing: {dependency}")]
     MissingDependency { dependency: String },
+    
+    #[error("Screen capture permission denied by {backend}")]
+    PermissionDenied { backend: String },
+}
+
+/// Stage at which reaching the server failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionErrorKind {
+    /// The server's host name did not resolve
+    Dns,
+    /// Nothing accepted the connection: refused, unreachable or timed out
+    Unreachable,
+    /// TLS failed, including certificates that fail validation or pinning
+    Tls,
+    /// The server answered but not with a WebSocket the agent can speak
+    Handshake,
+    /// An established connection went away
+    Closed,
+    /// The server turned this agent away
+    AuthRejected,
+    /// The proxy between agent and server could not be reached
+    ProxyUnreachable,
+    /// The proxy would not open a tunnel to the server, including for
+    /// missing or wrong proxy credentials
+    ProxyRefused,
+}
+
+impl fmt::Display for ConnectionErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionErrorKind::Dns => "DNS lookup failed",
+            ConnectionErrorKind::Unreachable => "server unreachable",
+            ConnectionErrorKind::Tls => "TLS failed",
+            ConnectionErrorKind::Handshake => "handshake failed",
+            ConnectionErrorKind::Closed => "connection closed",
+            ConnectionErrorKind::AuthRejected => "agent rejected",
+            ConnectionErrorKind::ProxyUnreachable => "proxy unreachable",
+            ConnectionErrorKind::ProxyRefused => "proxy refused CONNECT",
+        })
+    }
 }
 
 /// Connection related errors
@@ -100,14 +160,14 @@ pub enum ConnectionError {
     #[error("Not connected to server")]
     NotConnected,
     
-    #[error("Connection failed: {reason}")]
-    ConnectionFailed { reason: String },
+    #[error("Connection to {url} failed ({kind}): {reason}")]
+    ConnectionFailed { kind: ConnectionErrorKind, url: String, reason: String },
     
-    #[error("Authentication failed: {reason}")]
-    AuthenticationFailed { reason: String },
+    #[error("Server rejected agent {agent_id}: {reason}")]
+    AuthenticationFailed { agent_id: String, reason: String },
     
-    #[error("Invalid server URL: {url}")]
-    InvalidServerUrl { url: String },
+    #[error("Invalid server URL {url}: {reason}")]
+    InvalidServerUrl { url: String, reason: String },
     
     #[error("Message send failed: {reason}")]
     SendFailed { reason: String },
@@ -117,6 +177,43 @@ pub enum ConnectionError {
     
     #[error("Protocol version mismatch: client={client}, server={server}")]
     ProtocolMismatch { client: String, server: String },
+    
+    #[error("Certificate of {host} does not match any pinned key (presented SPKI sha256 {presented})")]
+    CertificatePinMismatch { host: String, presented: String },
+}
+
+impl ConnectionError {
+    /// Stage the connection failed at; `None` for a URL that is wrong
+    /// before anything is sent
+    pub fn kind(&self) -> Option<ConnectionErrorKind> {
+        match self {
+            ConnectionError::ConnectionFailed { kind, .. } => Some(*kind),
+            ConnectionError::AuthenticationFailed { .. } => Some(ConnectionErrorKind::AuthRejected),
+            ConnectionError::CertificatePinMismatch { .. } => Some(ConnectionErrorKind::Tls),
+            ConnectionError::ProtocolMismatch { .. } => Some(ConnectionErrorKind::Handshake),
+            ConnectionError::NotConnected
+            | ConnectionError::SendFailed { .. }
+            | ConnectionError::HeartbeatTimeout => Some(ConnectionErrorKind::Closed),
+            ConnectionError::InvalidServerUrl { .. } => None,
+        }
+    }
+    
+    /// Whether trying again later may succeed; a rejected agent, a bad URL
+    /// or a certificate problem needs someone to change the setup first.
+    /// Proxies are retried, since they refuse tunnels during outages too.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            Some(
+                ConnectionErrorKind::Dns
+                    | ConnectionErrorKind::Unreachable
+                    | ConnectionErrorKind::Handshake
+                    | ConnectionErrorKind::Closed
+                    | ConnectionErrorKind::ProxyUnreachable
+                    | ConnectionErrorKind::ProxyRefused
+            )
+        )
+    }
 }
 
 /// Session management errors
@@ -134,11 +231,21 @@ pub enum SessionError {
     #[error("Permission denied for session operation")]
     PermissionDenied,
     
-    #[error("Screen capture not available for session")]
-    CaptureNotAvailable,
+    #[error("Screen capture not available for session {session_id}")]
+    CaptureNotAvailable { session_id: String },
+    
+    #[error("{feature} is disabled by policy in session {session_id}")]
+    DisabledByPolicy { session_id: String, feature: String },
     
     #[error("Session already active: {session_id}")]
     AlreadyActive { session_id: String },
+    
+    #[error("Session token for {session_id} rejected: {reason}")]
+    InvalidToken { session_id: String, reason: String },
+    
+    /// A pre-flight check failed; `reason` is its diagnostic
+    #[error("{reason}")]
+    NotReady { reason: String, readiness: SessionReadiness },
 }
 
 /// Input control errors
@@ -161,6 +268,12 @@ pub enum InputError {
     
     #[error("Required tool missing: {tool}")]
     ToolMissing { tool: String },
+    
+    #[error("{operation} is not supported on {platform}")]
+    Unsupported { operation: String, platform: String },
+    
+    #[error("Input injection failed: {reason}")]
+    InjectionFailed { reason: String },
 }
 
 /// Service management errors
@@ -194,13 +307,247 @@ pub enum ConfigError {
     #[error("Missing required field: {field}")]
     MissingField { field: String },
     
-    #[error("Invalid value for {field}: {value}")]
-    InvalidValue { field: String, value: String },
+    #[error("Invalid value for {field}: {reason}")]
+    InvalidValue { field: String, reason: String },
+    
+    /// A value the config file's parser or checks rejected, where it sits
+    #[error("{path}:{line}:{column}: {reason}")]
+    Invalid { path: String, line: usize, column: usize, reason: String },
+}
+
+/// Privilege elevation errors
+#[derive(Error, Debug)]
+pub enum ElevationError {
+    #[error("Elevation denied: {reason}")]
+    Denied { reason: String },
+    
+    #[error("Elevation method not available: {reason}")]
+    Unavailable { reason: String },
+    
+    #[error("Elevation failed: {reason}")]
+    Failed { reason: String },
+}
+
+/// Why a toolbox tool did not run
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("Tool '{tool}' not found")]
+    NotFound { tool: String },
+    
+    #[error("Program of tool '{tool}' is missing: {program}")]
+    ProgramMissing { tool: String, program: String },
+    
+    #[error("Tool '{tool}' is blocked by policy: {reason}")]
+    BlockedByPolicy { tool: String, reason: String },
+    
+    #[error("Tool '{tool}' requires elevation: {reason}")]
+    ElevationRequired { tool: String, reason: String },
+    
+    #[error("Tool '{tool}' failed: {reason}")]
+    ExecutionFailed { tool: String, reason: String },
+}
+
+/// Why a file transfer was refused or broke off
+#[derive(Error, Debug)]
+pub enum FileTransferError {
+    #[error("{feature} disabled by policy")]
+    Disabled { feature: String },
+    
+    #[error("Path {path} is outside the allowed transfer roots")]
+    OutsideRoots { path: String },
+    
+    #[error("Transfer {transfer_id} not found")]
+    NotFound { transfer_id: String },
+    
+    #[error("Transfer {transfer_id} rejected: {reason}")]
+    Rejected { transfer_id: String, reason: String },
+    
+    #[error("Checksum mismatch for {filename}")]
+    ChecksumMismatch { filename: String },
+}
+
+/// Why a session pre-flight check failed, worded for the technician
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum PreflightError {
+    #[error("Screen capture permission denied by {backend}")]
+    CapturePermissionDenied { backend: String },
+    
+    #[error("{permission} permission missing: {remediation}")]
+    PermissionMissing { permission: String, remediation: String },
+    
+    #[error("Screen capture unavailable: {reason}")]
+    CaptureUnavailable { reason: String },
+    
+    #[error("No frame captured: {reason}")]
+    FrameGrabFailed { reason: String },
+    
+    #[error("No usable video encoder: {reason}")]
+    EncoderUnavailable { reason: String },
+    
+    #[error("Input injection unavailable: {reason}")]
+    InputUnavailable { reason: String },
+    
+    #[error("Relay unreachable: {reason}")]
+    RelayUnreachable { reason: String },
+    
+    #[error("{check} did not finish within {seconds}s")]
+    TimedOut { check: String, seconds: u64 },
+    
+    #[error("Not run, {check} failed")]
+    Skipped { check: String },
+}
+
+impl PreflightError {
+    /// What the viewer can act on beyond the message
+    pub fn code(&self) -> Option<FailureCode> {
+        match self {
+            PreflightError::PermissionMissing { .. } => Some(FailureCode::PermissionMissing),
+            _ => None,
+        }
+    }
+}
+
+impl GhostLinkError {
+    /// Exit code for the CLI, after sysexits(3), so scripts and service
+    /// managers can tell a problem worth retrying from one that is not
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            GhostLinkError::Connection(e) => match e.kind() {
+                Some(ConnectionErrorKind::AuthRejected) => EX_NOPERM,
+                Some(ConnectionErrorKind::Tls) | None => EX_CONFIG,
+                Some(ConnectionErrorKind::Handshake) => EX_PROTOCOL,
+                Some(_) => EX_TEMPFAIL,
+            },
+            GhostLinkError::Config(_) => EX_CONFIG,
+            GhostLinkError::Elevation(_)
+            | GhostLinkError::Service(ServiceError::InsufficientPrivileges)
+            | GhostLinkError::Toolbox(ToolError::BlockedByPolicy { .. } | ToolError::ElevationRequired { .. }) => EX_NOPERM,
+            GhostLinkError::Toolbox(ToolError::NotFound { .. } | ToolError::ProgramMissing { .. }) => EX_NOINPUT,
+            GhostLinkError::Session(_) | GhostLinkError::Preflight(_) | GhostLinkError::Capture(_) => EX_UNAVAILABLE,
+            _ => 1,
+        }
+    }
+    
+    /// Whether the same request may succeed if tried again later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            GhostLinkError::Connection(e) => e.is_retryable(),
+            GhostLinkError::WebSocket(_) => true,
+            _ => false,
+        }
+    }
+    
+    /// Prefix the message of an error that has no variant of its own;
+    /// typed errors are returned as they are so callers can still match on
+    /// them, and I/O errors keep their kind
+    pub fn context(self, context: impl fmt::Display) -> Self {
+        match self {
+            GhostLinkError::Io(e) => GhostLinkError::Io(std::io::Error::new(e.kind(), format!("{}: {}", context, e))),
+            GhostLinkError::Encode(_)
+            | GhostLinkError::Protocol(_)
+            | GhostLinkError::Serialization(_)
+            | GhostLinkError::WebSocket(_)
+            | GhostLinkError::Tls(_)
+            | GhostLinkError::X11(_)
+            | GhostLinkError::X11Connection(_)
+            | GhostLinkError::X11Reply(_)
+            | GhostLinkError::X11Generic(_)
+            | GhostLinkError::Other(_) => GhostLinkError::Other(format!("{}: {}", context, self)),
+            typed => typed,
+        }
+    }
+}
+
+const EX_NOINPUT: u8 = 66;
+const EX_UNAVAILABLE: u8 = 69;
+const EX_TEMPFAIL: u8 = 75;
+const EX_PROTOCOL: u8 = 76;
+const EX_NOPERM: u8 = 77;
+const EX_CONFIG: u8 = 78;
+
+/// `anyhow::Context` for the crate's `Result`, see [`GhostLinkError::context`]
+pub trait Context<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T>;
+    
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl<T, E: Into<GhostLinkError>> Context<T> for std::result::Result<T, E> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+    
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|e| e.into().context(context()))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.ok_or_else(|| GhostLinkError::Other(context.to_string()))
+    }
+    
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.ok_or_else(|| GhostLinkError::Other(context().to_string()))
+    }
 }
 
 impl From<anyhow::Error> for GhostLinkError {
     fn from(error: anyhow::Error) -> Self {
-        GhostLinkError::Other(error.to_string())
+        match error.downcast::<GhostLinkError>() {
+            Ok(typed) => typed,
+            Err(error) => GhostLinkError::Other(format!("{:#}", error)),
+        }
+    }
+}
+
+impl From<reqwest::Error> for GhostLinkError {
+    fn from(error: reqwest::Error) -> Self {
+        let url = error.url().map(|url| url.to_string()).unwrap_or_default();
+        let kind = match error.status() {
+            Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN) => ConnectionErrorKind::AuthRejected,
+            Some(_) => return GhostLinkError::Protocol(error.to_string()),
+            None if error.is_connect() || error.is_timeout() => ConnectionErrorKind::Unreachable,
+            None if error.is_decode() => return GhostLinkError::Protocol(error.to_string()),
+            None => ConnectionErrorKind::Closed,
+        };
+        GhostLinkError::Connection(ConnectionError::ConnectionFailed { kind, url, reason: error.to_string() })
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for GhostLinkError {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        GhostLinkError::WebSocket(Box::new(error))
+    }
+}
+
+impl From<crate::connection::protocol::DecodeError> for GhostLinkError {
+    fn from(error: crate::connection::protocol::DecodeError) -> Self {
+        GhostLinkError::Protocol(error.to_string())
+    }
+}
+
+impl From<crate::connection::outbound::QueueClosed> for GhostLinkError {
+    fn from(_: crate::connection::outbound::QueueClosed) -> Self {
+        GhostLinkError::Connection(ConnectionError::NotConnected)
+    }
+}
+
+impl From<tokio::task::JoinError> for GhostLinkError {
+    fn from(error: tokio::task::JoinError) -> Self {
+        GhostLinkError::Other(format!("Background task failed: {}", error))
+    }
+}
+
+impl From<base64::DecodeError> for GhostLinkError {
+    fn from(error: base64::DecodeError) -> Self {
+        GhostLinkError::Protocol(format!("Invalid base64: {}", error))
+    }
+}
+
+impl From<uuid::Error> for GhostLinkError {
+    fn from(error: uuid::Error) -> Self {
+        GhostLinkError::Protocol(format!("Invalid UUID: {}", error))
     }
 }
 
@@ -217,4 +564,37 @@ impl From<&str> for GhostLinkError {
 }
 
 /// Type alias for Results using GhostLinkError
-pub type Result<T> = std::result::Result<T, GhostLinkError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, GhostLinkError>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection_failed(kind: ConnectionErrorKind) -> GhostLinkError {
+        ConnectionError::ConnectionFailed { kind, url: "wss://relay".to_string(), reason: "test".to_string() }.into()
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(connection_failed(ConnectionErrorKind::Unreachable).exit_code(), 75);
+        assert_eq!(connection_failed(ConnectionErrorKind::Handshake).exit_code(), 76);
+        assert_eq!(connection_failed(ConnectionErrorKind::AuthRejected).exit_code(), 77);
+        assert_eq!(connection_failed(ConnectionErrorKind::Tls).exit_code(), 78);
+
+        let not_found: GhostLinkError = ToolError::NotFound { tool: "ping".to_string() }.into();
+        assert_eq!(not_found.exit_code(), 66);
+        let blocked: GhostLinkError = ToolError::BlockedByPolicy { tool: "ping".to_string(), reason: "test".to_string() }.into();
+        assert_eq!(blocked.exit_code(), 77);
+        let failed: GhostLinkError = ToolError::ExecutionFailed { tool: "ping".to_string(), reason: "exited with code 2".to_string() }.into();
+        assert_eq!(failed.exit_code(), 1);
+
+        let unavailable: GhostLinkError = PreflightError::CaptureUnavailable { reason: "test".to_string() }.into();
+        assert_eq!(unavailable.exit_code(), 69);
+        assert_eq!(GhostLinkError::from("anything else").exit_code(), 1);
+    }
+
+    #[test]
+    fn test_exit_code_survives_anyhow() {
+        let typed: anyhow::Error = GhostLinkError::from(ToolError::NotFound { tool: "ping".to_string() }).into();
+        assert_eq!(GhostLinkError::from(typed).exit_code(), 66);
+    }
+}
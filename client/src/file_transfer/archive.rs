@@ -0,0 +1,368 @@
+//! Streamed tar.zst export of a remote directory
+//!
+//! The archive is produced on a blocking thread and handed out in fixed-size
+//! chunks over a channel, so it is never held in memory as a whole.
+
+use crate::error::{ConnectionError, Context, GhostLinkError, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use super::FileTransferPolicy;
+use crate::connection::{MessagePriority, RelayConnection, RelayMessage};
+
+/// Magic prefix identifying archive chunks on the binary channel
+pub const ARCHIVE_CHUNK_MAGIC: &[u8; 4] = b"GLAR";
+
+/// Size of each binary chunk sent to the server
+pub const ARCHIVE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Name of the manifest entry listing skipped paths
+pub const SKIP_MANIFEST_NAME: &str = "GHOSTLINK_SKIPPED.txt";
+
+/// zstd compression level used for archives
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Parameters of a `CollectArchive` command
+#[derive(Debug, Clone)]
+pub struct ArchiveRequest {
+    pub root: PathBuf,
+    pub include_globs: Vec<String>,
+    pub max_size: u64,
+}
+
+/// A path left out of the archive and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedEntry {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Outcome of a completed archive
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveSummary {
+    pub files: usize,
+    pub bytes: u64,
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// Encode an archive chunk for the binary channel:
+/// magic (4) | request id (16) | last flag (1) | payload
+pub fn encode_archive_chunk(request_id: Uuid, last: bool, data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(21 + data.len());
+    chunk.extend_from_slice(ARCHIVE_CHUNK_MAGIC);
+    chunk.extend_from_slice(request_id.as_bytes());
+    chunk.push(last as u8);
+    chunk.extend_from_slice(data);
+    chunk
+}
+
+/// `Write` adapter that forwards fixed-size chunks over a channel
+struct ChunkWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(tx: mpsc::Sender<Vec<u8>>) -> Self {
+        Self {
+            tx,
+            buffer: Vec::with_capacity(ARCHIVE_CHUNK_SIZE),
+        }
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) -> io::Result<()> {
+        self.tx.blocking_send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "archive receiver closed"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= ARCHIVE_CHUNK_SIZE {
+            let rest = self.buffer.split_off(ARCHIVE_CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            self.send(chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let chunk = std::mem::take(&mut self.buffer);
+            self.send(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Build the archive for `request` and stream it through `chunk_tx`.
+///
+/// Must be called from a blocking context (e.g. `spawn_blocking`).
+pub fn stream_archive(
+    request: &ArchiveRequest,
+    policy: &FileTransferPolicy,
+    chunk_tx: mpsc::Sender<Vec<u8>>,
+) -> Result<ArchiveSummary> {
+    policy.check_path(&request.root)?;
+    let size_cap = policy.effective_size_cap(request.max_size);
+    
+    let mut writer = ChunkWriter::new(chunk_tx);
+    let summary = write_archive(&mut writer, &request.root, &request.include_globs, size_cap)?;
+    writer.flush()?;
+    
+    Ok(summary)
+}
+
+/// Write a tar.zst of the files under `root` matching `include_globs` into `writer`.
+///
+/// Fails once the archived file contents would exceed `size_cap` bytes.
+pub fn write_archive<W: Write>(
+    writer: W,
+    root: &Path,
+    include_globs: &[String],
+    size_cap: u64,
+) -> Result<ArchiveSummary> {
+    let root = root.canonicalize()
+        .with_context(|| format!("Archive root not found: {}", root.display()))?;
+    let patterns = include_globs.iter()
+        .map(|g| glob::Pattern::new(g).map_err(|e| GhostLinkError::Other(format!("Invalid glob {}: {}", g, e))))
+        .collect::<Result<Vec<_>>>()?;
+    
+    let encoder = zstd::stream::write::Encoder::new(writer, COMPRESSION_LEVEL)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    
+    let mut summary = ArchiveSummary::default();
+    let mut pending = vec![root.clone()];
+    
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        
+        for path in entries {
+            let relative = path.strip_prefix(&root).unwrap_or(&path).to_path_buf();
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    summary.skip(&relative, &format!("unreadable: {}", e));
+                    continue;
+                }
+            };
+            let file_type = metadata.file_type();
+            
+            if file_type.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            
+            if !patterns.is_empty() && !patterns.iter().any(|p| p.matches_path(&relative)) {
+                continue;
+            }
+            
+            if file_type.is_symlink() {
+                match path.canonicalize() {
+                    Ok(target) if target.starts_with(&root) => {
+                        builder.append_path_with_name(&path, &relative)?;
+                    }
+                    _ => summary.skip(&relative, "symlink outside archive root"),
+                }
+            } else if file_type.is_file() {
+                if summary.bytes + metadata.len() > size_cap {
+                    return Err(GhostLinkError::Other(format!(
+                        "Archive exceeds size cap of {} bytes at {}", size_cap, relative.display()
+                    )));
+                }
+                builder.append_path_with_name(&path, &relative)?;
+                summary.files += 1;
+                summary.bytes += metadata.len();
+            } else {
+                summary.skip(&relative, "special file");
+            }
+        }
+    }
+    
+    if !summary.skipped.is_empty() {
+        let manifest = summary.skipped.iter()
+            .map(|s| format!("{}\t{}\n", s.path, s.reason))
+            .collect::<String>();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, SKIP_MANIFEST_NAME, manifest.as_bytes())?;
+    }
+    
+    builder.into_inner()?.finish()?;
+    
+    debug!("Archive complete: {} files, {} bytes, {} skipped",
+        summary.files, summary.bytes, summary.skipped.len());
+    Ok(summary)
+}
+
+impl ArchiveSummary {
+    fn skip(&mut self, path: &Path, reason: &str) {
+        self.skipped.push(SkippedEntry {
+            path: path.display().to_string(),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+/// Build an archive in the background and upload it to the server in chunks
+pub fn spawn_archive_upload(
+    connection: Arc<RwLock<Option<RelayConnection>>>,
+    policy: FileTransferPolicy,
+    request_id: String,
+    request: ArchiveRequest,
+) {
+    tokio::spawn(async move {
+        info!("Collecting archive {} from {}", request_id, request.root.display());
+        
+        let result = upload_archive(&connection, policy, &request_id, request).await;
+        
+        if let Err(e) = result {
+            error!("Archive {} failed: {}", request_id, e);
+            let conn_guard = connection.read().await;
+            if let Some(conn) = conn_guard.as_ref() {
+                let _ = conn.send_message(RelayMessage::ArchiveError {
+                    request_id,
+                    message: e.to_string(),
+                }).await;
+            }
+        }
+    });
+}
+
+async fn upload_archive(
+    connection: &Arc<RwLock<Option<RelayConnection>>>,
+    policy: FileTransferPolicy,
+    request_id: &str,
+    request: ArchiveRequest,
+) -> Result<()> {
+    let request_uuid = Uuid::parse_str(request_id).context("Invalid archive request ID")?;
+    
+    let (chunk_tx, mut chunk_rx) = mpsc::channel::<Vec<u8>>(8);
+    let archive_task = tokio::task::spawn_blocking(move || {
+        stream_archive(&request, &policy, chunk_tx)
+    });
+    
+    while let Some(chunk) = chunk_rx.recv().await {
+        let conn_guard = connection.read().await;
+        let conn = conn_guard.as_ref().ok_or(ConnectionError::NotConnected)?;
+        conn.send_binary(encode_archive_chunk(request_uuid, false, &chunk), MessagePriority::Low).await?;
+    }
+    
+    let summary = archive_task.await.context("Archive task panicked")??;
+    if !summary.skipped.is_empty() {
+        warn!("Archive {} skipped {} entries", request_id, summary.skipped.len());
+    }
+    
+    let conn_guard = connection.read().await;
+    let conn = conn_guard.as_ref().ok_or(ConnectionError::NotConnected)?;
+    conn.send_binary(encode_archive_chunk(request_uuid, true, &[]), MessagePriority::Low).await?;
+    
+    info!("Archive {} uploaded: {} files, {} bytes", request_id, summary.files, summary.bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    fn fixture() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("app.log"), b"application log").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"not a log").unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested/system.log"), b"system log").unwrap();
+        dir
+    }
+
+    fn entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let tar_data = zstd::decode_all(archive).unwrap();
+        let mut tar = tar::Archive::new(tar_data.as_slice());
+        let mut out = tar.entries().unwrap()
+            .map(|e| {
+                let mut entry = e.unwrap();
+                let name = entry.path().unwrap().display().to_string();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                (name, content)
+            })
+            .collect::<Vec<_>>();
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn test_archive_matches_globs() {
+        let dir = fixture();
+        let mut archive = Vec::new();
+        let summary = write_archive(&mut archive, dir.path(), &["*.log".to_string()], u64::MAX).unwrap();
+
+        assert_eq!(summary.files, 2);
+        let names = entries(&archive).into_iter().map(|(n, _)| n).collect::<Vec<_>>();
+        assert_eq!(names, vec!["app.log", "nested/system.log"]);
+    }
+
+    #[test]
+    fn test_archive_contents_roundtrip() {
+        let dir = fixture();
+        let mut archive = Vec::new();
+        write_archive(&mut archive, dir.path(), &[], u64::MAX).unwrap();
+
+        let entries = entries(&archive);
+        assert_eq!(entries.len(), 3);
+        assert!(entries.contains(&("notes.txt".to_string(), b"not a log".to_vec())));
+    }
+
+    #[test]
+    fn test_archive_size_cap_aborts() {
+        let dir = fixture();
+        let result = write_archive(Vec::new(), dir.path(), &[], 16);
+        assert!(result.unwrap_err().to_string().contains("size cap"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_outside_root_are_skipped() {
+        let dir = fixture();
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), dir.path().join("escape")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("app.log"), dir.path().join("inside")).unwrap();
+
+        let mut archive = Vec::new();
+        let summary = write_archive(&mut archive, dir.path(), &[], u64::MAX).unwrap();
+
+        assert_eq!(summary.skipped, vec![SkippedEntry {
+            path: "escape".to_string(),
+            reason: "symlink outside archive root".to_string(),
+        }]);
+        let names = entries(&archive).into_iter().map(|(n, _)| n).collect::<Vec<_>>();
+        assert!(names.contains(&"inside".to_string()));
+        assert!(names.contains(&SKIP_MANIFEST_NAME.to_string()));
+        assert!(!names.contains(&"escape".to_string()));
+    }
+
+    #[test]
+    fn test_chunk_encoding() {
+        let id = Uuid::new_v4();
+        let chunk = encode_archive_chunk(id, true, b"data");
+        assert_eq!(&chunk[..4], ARCHIVE_CHUNK_MAGIC);
+        assert_eq!(&chunk[4..20], id.as_bytes());
+        assert_eq!(chunk[20], 1);
+        assert_eq!(&chunk[21..], b"data");
+    }
+}
@@ -0,0 +1,624 @@
+//! Sandboxed browsing of this machine's filesystem for the technician's file panel
+//!
+//! The technician lists, inspects, creates, renames and deletes entries with
+//! `ListDirectory`, `StatEntry`, `CreateDirectory`, `Rename` and `Delete`,
+//! each answered by an `FsResult` carrying the same request ID.
+//!
+//! Every path must be absolute. It is normalized lexically first, then its
+//! parent is resolved on disk, so `..` and symlinked directories cannot lead
+//! out of the allowed roots. The final component is not followed for stat,
+//! rename and delete, which act on a symlink itself, but it is for listing,
+//! so a link pointing outside the roots cannot be opened. Sessions browse the
+//! policy's `browse_roots` (the user profiles by default) and elevated ones
+//! its `elevated_browse_roots`, where an empty list means the whole machine.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::FileTransferPolicy;
+use crate::connection::RelayMessage;
+
+/// Entries per page when the request does not say
+pub const DEFAULT_PAGE_SIZE: usize = 500;
+
+/// Largest page a request may ask for
+pub const MAX_PAGE_SIZE: usize = 5000;
+
+/// Why a file browser request failed, as reported to the technician
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FsError {
+    #[error("File browsing is unavailable: {reason}")]
+    Unavailable { reason: String },
+
+    #[error("Invalid path '{path}': {reason}")]
+    InvalidPath { path: String, reason: String },
+
+    #[error("{path} is outside the directories this session may browse")]
+    OutsideRoots { path: String },
+
+    #[error("{path} does not exist")]
+    NotFound { path: String },
+
+    #[error("Permission denied: {path}")]
+    PermissionDenied { path: String },
+
+    #[error("{path} is not a directory")]
+    NotADirectory { path: String },
+
+    #[error("{path} already exists")]
+    AlreadyExists { path: String },
+
+    #[error("{path} is not empty")]
+    NotEmpty { path: String },
+
+    #[error("I/O error on {path}: {message}")]
+    Io { path: String, message: String },
+}
+
+impl FsError {
+    fn from_io(path: &str, error: io::Error) -> Self {
+        let path = path.to_string();
+        match error.kind() {
+            io::ErrorKind::NotFound => FsError::NotFound { path },
+            io::ErrorKind::PermissionDenied => FsError::PermissionDenied { path },
+            io::ErrorKind::AlreadyExists => FsError::AlreadyExists { path },
+            _ => FsError::Io { path, message: error.to_string() },
+        }
+    }
+}
+
+/// A file, directory or symlink as shown in the file panel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FsEntry {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    /// Last modification in seconds since the Unix epoch
+    pub modified: Option<i64>,
+    /// `rwxr-xr-x` style on Unix, `r--` or `rw-` elsewhere
+    pub permissions: String,
+    pub readonly: bool,
+    /// Also set for symlinks to directories
+    pub is_dir: bool,
+    pub is_symlink: bool,
+}
+
+/// One page of a directory, directories first and then by name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectoryPage {
+    pub path: String,
+    pub entries: Vec<FsEntry>,
+    pub offset: usize,
+    /// Entries in the whole directory
+    pub total: usize,
+    /// Offset of the next page, if there is one
+    pub next_offset: Option<usize>,
+}
+
+/// A file browser operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsRequest {
+    List { path: String, offset: usize, limit: Option<usize> },
+    Stat { path: String },
+    CreateDirectory { path: String },
+    Rename { from: String, to: String },
+    Delete { path: String, recursive: bool },
+}
+
+impl FsRequest {
+    /// Split a file browser message into its session, request ID and operation
+    pub fn from_message(message: RelayMessage) -> Option<(String, String, FsRequest)> {
+        match message {
+            RelayMessage::ListDirectory { session_id, request_id, path, offset, limit } => {
+                Some((session_id, request_id, FsRequest::List { path, offset, limit }))
+            }
+            RelayMessage::StatEntry { session_id, request_id, path } => {
+                Some((session_id, request_id, FsRequest::Stat { path }))
+            }
+            RelayMessage::CreateDirectory { session_id, request_id, path } => {
+                Some((session_id, request_id, FsRequest::CreateDirectory { path }))
+            }
+            RelayMessage::Rename { session_id, request_id, from, to } => {
+                Some((session_id, request_id, FsRequest::Rename { from, to }))
+            }
+            RelayMessage::Delete { session_id, request_id, path, recursive } => {
+                Some((session_id, request_id, FsRequest::Delete { path, recursive }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What a successful operation returns
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FsResponse {
+    Listing(DirectoryPage),
+    /// The entry stat'ed, created or renamed
+    Entry(FsEntry),
+    Deleted { path: String },
+}
+
+/// The `FsResult` answering a request
+pub fn result_message(session_id: &str, request_id: &str, result: Result<FsResponse, FsError>) -> RelayMessage {
+    let (result, error) = match result {
+        Ok(response) => (Some(response), None),
+        Err(e) => (None, Some(e)),
+    };
+    RelayMessage::FsResult {
+        session_id: session_id.to_string(),
+        request_id: request_id.to_string(),
+        success: error.is_none(),
+        result,
+        error,
+    }
+}
+
+/// Lexically normalize an absolute path: drop `.` and resolve `..` against
+/// the component before it, never climbing above the filesystem root
+pub fn normalize(path: &str) -> Result<PathBuf, FsError> {
+    let invalid = |reason: &str| FsError::InvalidPath { path: path.to_string(), reason: reason.to_string() };
+    if path.contains('\0') {
+        return Err(invalid("contains a NUL byte"));
+    }
+    if !Path::new(path).is_absolute() {
+        return Err(invalid("must be absolute"));
+    }
+
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => normalized.push(component.as_os_str()),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+        }
+    }
+    Ok(normalized)
+}
+
+/// Executes file browser requests for one session
+pub struct FileBrowser {
+    enabled: bool,
+    /// Canonical roots the session may browse; `None` is unrestricted
+    roots: Option<Vec<PathBuf>>,
+}
+
+impl FileBrowser {
+    pub fn new(policy: &FileTransferPolicy, elevated: bool) -> Self {
+        let configured = if elevated { &policy.elevated_browse_roots } else { &policy.browse_roots };
+        let roots = if elevated && configured.is_empty() {
+            None
+        } else {
+            Some(configured.iter().filter_map(|root| root.canonicalize().ok()).collect())
+        };
+        Self { enabled: policy.enabled, roots }
+    }
+
+    pub fn execute(&self, request: &FsRequest) -> Result<FsResponse, FsError> {
+        if !self.enabled {
+            return Err(FsError::Unavailable { reason: "file transfer is disabled by policy".to_string() });
+        }
+        match request {
+            FsRequest::List { path, offset, limit } => self.list(path, *offset, *limit).map(FsResponse::Listing),
+            FsRequest::Stat { path } => self.stat(path).map(FsResponse::Entry),
+            FsRequest::CreateDirectory { path } => self.create_directory(path).map(FsResponse::Entry),
+            FsRequest::Rename { from, to } => self.rename(from, to).map(FsResponse::Entry),
+            FsRequest::Delete { path, recursive } => {
+                self.delete(path, *recursive).map(|()| FsResponse::Deleted { path: path.clone() })
+            }
+        }
+    }
+
+    /// List a directory a page at a time; an empty path lists the roots
+    pub fn list(&self, path: &str, offset: usize, limit: Option<usize>) -> Result<DirectoryPage, FsError> {
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        if path.is_empty() {
+            let roots: Vec<FsEntry> = self.root_paths().iter()
+                .filter_map(|root| Some(entry(root, &fs::metadata(root).ok()?)))
+                .collect();
+            return Ok(page(String::new(), roots, offset, limit));
+        }
+
+        let dir = self.resolve_target(path)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory { path: path.to_string() });
+        }
+
+        // Only names and types are read for the whole directory; the entries
+        // of the requested page are stat'ed one by one
+        let mut children: Vec<(bool, String)> = fs::read_dir(&dir)
+            .map_err(|e| FsError::from_io(path, e))?
+            .filter_map(|child| child.ok())
+            .map(|child| {
+                let is_dir = child.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                (is_dir, child.file_name().to_string_lossy().into_owned())
+            })
+            .collect();
+        children.sort_by_cached_key(|(is_dir, name)| (!is_dir, name.to_lowercase(), name.clone()));
+
+        let total = children.len();
+        let entries = children.iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(_, name)| {
+                let child = dir.join(name);
+                Some(entry(&child, &fs::symlink_metadata(&child).ok()?))
+            })
+            .collect();
+        Ok(DirectoryPage {
+            path: dir.display().to_string(),
+            entries,
+            offset,
+            total,
+            next_offset: (offset + limit < total).then_some(offset + limit),
+        })
+    }
+
+    /// Describe an entry without following it if it is a symlink
+    pub fn stat(&self, path: &str) -> Result<FsEntry, FsError> {
+        let resolved = self.resolve_entry(path)?;
+        let metadata = fs::symlink_metadata(&resolved).map_err(|e| FsError::from_io(path, e))?;
+        Ok(entry(&resolved, &metadata))
+    }
+
+    /// Create a directory whose parent exists
+    pub fn create_directory(&self, path: &str) -> Result<FsEntry, FsError> {
+        let resolved = self.resolve_entry(path)?;
+        fs::create_dir(&resolved).map_err(|e| FsError::from_io(path, e))?;
+        self.stat(path)
+    }
+
+    /// Rename an entry; an existing entry at `to` is never replaced
+    pub fn rename(&self, from: &str, to: &str) -> Result<FsEntry, FsError> {
+        let source = self.resolve_entry(from)?;
+        let target = self.resolve_entry(to)?;
+        self.check_not_root(&source, from)?;
+        fs::symlink_metadata(&source).map_err(|e| FsError::from_io(from, e))?;
+        if fs::symlink_metadata(&target).is_ok() {
+            return Err(FsError::AlreadyExists { path: to.to_string() });
+        }
+        fs::rename(&source, &target).map_err(|e| FsError::from_io(from, e))?;
+        self.stat(to)
+    }
+
+    /// Delete a file, symlink or directory; non-empty directories only when `recursive`
+    pub fn delete(&self, path: &str, recursive: bool) -> Result<(), FsError> {
+        let resolved = self.resolve_entry(path)?;
+        self.check_not_root(&resolved, path)?;
+        let metadata = fs::symlink_metadata(&resolved).map_err(|e| FsError::from_io(path, e))?;
+        let result = if !metadata.is_dir() {
+            fs::remove_file(&resolved)
+        } else if recursive {
+            // Does not follow symlinks inside the tree
+            fs::remove_dir_all(&resolved)
+        } else {
+            let mut children = fs::read_dir(&resolved).map_err(|e| FsError::from_io(path, e))?;
+            if children.next().is_some() {
+                return Err(FsError::NotEmpty { path: path.to_string() });
+            }
+            fs::remove_dir(&resolved)
+        };
+        result.map_err(|e| FsError::from_io(path, e))
+    }
+
+    /// Resolve `path` with its parent canonicalized but its last component as
+    /// given, and check the result lies inside the roots
+    fn resolve_entry(&self, path: &str) -> Result<PathBuf, FsError> {
+        let normalized = normalize(path)?;
+        let resolved = match (normalized.parent(), normalized.file_name()) {
+            (Some(parent), Some(name)) => {
+                parent.canonicalize().map_err(|e| FsError::from_io(path, e))?.join(name)
+            }
+            // A filesystem root
+            _ => normalized,
+        };
+        self.check_inside(&resolved, path)?;
+        Ok(resolved)
+    }
+
+    /// Resolve `path` following every symlink, and check where it leads
+    fn resolve_target(&self, path: &str) -> Result<PathBuf, FsError> {
+        let resolved = self.resolve_entry(path)?.canonicalize().map_err(|e| FsError::from_io(path, e))?;
+        self.check_inside(&resolved, path)?;
+        Ok(resolved)
+    }
+
+    fn check_inside(&self, resolved: &Path, path: &str) -> Result<(), FsError> {
+        match &self.roots {
+            Some(roots) if !roots.iter().any(|root| resolved.starts_with(root)) => {
+                Err(FsError::OutsideRoots { path: path.to_string() })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The roots themselves may be browsed but not renamed or deleted
+    fn check_not_root(&self, resolved: &Path, path: &str) -> Result<(), FsError> {
+        if self.root_paths().iter().any(|root| root == resolved) {
+            return Err(FsError::PermissionDenied { path: path.to_string() });
+        }
+        Ok(())
+    }
+
+    fn root_paths(&self) -> Vec<PathBuf> {
+        match &self.roots {
+            Some(roots) => roots.clone(),
+            None => filesystem_roots(),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn filesystem_roots() -> Vec<PathBuf> {
+    (b'A'..=b'Z')
+        .map(|letter| PathBuf::from(format!("{}:\\", letter as char)))
+        .filter(|drive| drive.exists())
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn filesystem_roots() -> Vec<PathBuf> {
+    vec![PathBuf::from("/")]
+}
+
+fn page(path: String, entries: Vec<FsEntry>, offset: usize, limit: usize) -> DirectoryPage {
+    let total = entries.len();
+    DirectoryPage {
+        path,
+        entries: entries.into_iter().skip(offset).take(limit).collect(),
+        offset,
+        total,
+        next_offset: (offset + limit < total).then_some(offset + limit),
+    }
+}
+
+fn entry(path: &Path, metadata: &Metadata) -> FsEntry {
+    let is_symlink = metadata.file_type().is_symlink();
+    let is_dir = if is_symlink {
+        fs::metadata(path).map(|target| target.is_dir()).unwrap_or(false)
+    } else {
+        metadata.is_dir()
+    };
+    let modified = metadata.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs() as i64);
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => path.display().to_string(),
+    };
+
+    FsEntry {
+        name,
+        path: path.display().to_string(),
+        size: if is_dir { 0 } else { metadata.len() },
+        modified,
+        permissions: permissions(metadata),
+        readonly: metadata.permissions().readonly(),
+        is_dir,
+        is_symlink,
+    }
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+    (0..9)
+        .map(|bit| {
+            let set = mode & (0o400 >> bit) != 0;
+            match (set, bit % 3) {
+                (false, _) => '-',
+                (true, 0) => 'r',
+                (true, 1) => 'w',
+                (true, _) => 'x',
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &Metadata) -> String {
+    if metadata.permissions().readonly() { "r--" } else { "rw-" }.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A browse root holding a few entries, next to a directory outside it
+    struct Fixture {
+        _dir: TempDir,
+        root: PathBuf,
+        outside: PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let root = dir.path().canonicalize().unwrap().join("profile");
+            let outside = dir.path().canonicalize().unwrap().join("system");
+            fs::create_dir_all(root.join("Documents")).unwrap();
+            fs::write(root.join("Documents/report.txt"), b"quarterly").unwrap();
+            fs::create_dir_all(&outside).unwrap();
+            fs::write(outside.join("secret"), b"secret").unwrap();
+            Self { _dir: dir, root, outside }
+        }
+
+        fn browser(&self) -> FileBrowser {
+            let policy = FileTransferPolicy {
+                browse_roots: vec![self.root.clone()],
+                ..FileTransferPolicy::default()
+            };
+            FileBrowser::new(&policy, false)
+        }
+
+        fn path(&self, relative: &str) -> String {
+            format!("{}/{}", self.root.display(), relative)
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/home/./alice/../bob").unwrap(), PathBuf::from("/home/bob"));
+        assert_eq!(normalize("/../../etc").unwrap(), PathBuf::from("/etc"));
+        assert!(matches!(normalize("home/bob"), Err(FsError::InvalidPath { .. })));
+        assert!(matches!(normalize(""), Err(FsError::InvalidPath { .. })));
+        assert!(matches!(normalize("/home/\0bob"), Err(FsError::InvalidPath { .. })));
+    }
+
+    #[test]
+    fn test_escapes_are_refused() {
+        let fixture = Fixture::new();
+        let browser = fixture.browser();
+
+        let climb = fixture.path("Documents/../../system");
+        assert_eq!(browser.list(&climb, 0, None), Err(FsError::OutsideRoots { path: climb.clone() }));
+        let secret = fixture.outside.join("secret").display().to_string();
+        assert_eq!(browser.delete(&secret, false), Err(FsError::OutsideRoots { path: secret }));
+        assert!(fixture.outside.join("secret").exists());
+
+        // Renaming something out of the roots is an escape too
+        let target = fixture.outside.join("report.txt").display().to_string();
+        let result = browser.rename(&fixture.path("Documents/report.txt"), &target);
+        assert_eq!(result, Err(FsError::OutsideRoots { path: target }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escapes_are_refused() {
+        let fixture = Fixture::new();
+        let browser = fixture.browser();
+        std::os::unix::fs::symlink(&fixture.outside, fixture.root.join("link")).unwrap();
+
+        // The link itself is visible and can be removed, but not followed
+        let link = browser.stat(&fixture.path("link")).unwrap();
+        assert!(link.is_symlink && link.is_dir);
+        let through = fixture.path("link");
+        assert_eq!(browser.list(&through, 0, None), Err(FsError::OutsideRoots { path: through }));
+        let nested = fixture.path("link/secret");
+        assert_eq!(browser.delete(&nested, false), Err(FsError::OutsideRoots { path: nested }));
+
+        browser.delete(&fixture.path("link"), true).unwrap();
+        assert!(fixture.outside.join("secret").exists());
+    }
+
+    #[test]
+    fn test_listing_is_paginated() {
+        let fixture = Fixture::new();
+        let browser = fixture.browser();
+        for i in 0..25 {
+            fs::write(fixture.root.join(format!("file-{:02}.log", i)), vec![0u8; i]).unwrap();
+        }
+
+        let first = browser.list(&fixture.path(""), 0, Some(10)).unwrap();
+        assert_eq!(first.total, 26);
+        assert_eq!(first.entries.len(), 10);
+        assert_eq!(first.next_offset, Some(10));
+        // Directories come first
+        assert!(first.entries[0].is_dir);
+        assert_eq!(first.entries[0].name, "Documents");
+        assert_eq!(first.entries[1].name, "file-00.log");
+
+        let mut names = Vec::new();
+        let mut offset = Some(0);
+        while let Some(next) = offset {
+            let page = browser.list(&fixture.path(""), next, Some(10)).unwrap();
+            names.extend(page.entries.into_iter().map(|entry| entry.name));
+            offset = page.next_offset;
+        }
+        assert_eq!(names.len(), 26);
+        assert_eq!(names.last().unwrap(), "file-24.log");
+
+        // The size of a page is bounded whatever the request asks for
+        let all = browser.list(&fixture.path(""), 0, Some(usize::MAX)).unwrap();
+        assert_eq!(all.entries.len(), 26);
+        assert_eq!(all.next_offset, None);
+
+        let report = browser.stat(&fixture.path("Documents/report.txt")).unwrap();
+        assert_eq!((report.size, report.is_dir), (9, false));
+        assert!(report.modified.is_some());
+    }
+
+    #[test]
+    fn test_errors_are_typed() {
+        let fixture = Fixture::new();
+        let browser = fixture.browser();
+
+        let missing = fixture.path("missing");
+        assert_eq!(browser.stat(&missing), Err(FsError::NotFound { path: missing }));
+        let file = fixture.path("Documents/report.txt");
+        assert_eq!(browser.list(&file, 0, None), Err(FsError::NotADirectory { path: file }));
+        let documents = fixture.path("Documents");
+        assert_eq!(browser.create_directory(&documents), Err(FsError::AlreadyExists { path: documents.clone() }));
+        assert_eq!(browser.delete(&documents, false), Err(FsError::NotEmpty { path: documents.clone() }));
+        let root = fixture.root.display().to_string();
+        assert_eq!(browser.delete(&root, true), Err(FsError::PermissionDenied { path: root }));
+
+        let disabled = FileBrowser::new(&FileTransferPolicy { enabled: false, ..FileTransferPolicy::default() }, false);
+        assert!(matches!(disabled.execute(&FsRequest::Stat { path: documents }), Err(FsError::Unavailable { .. })));
+
+        let json = serde_json::to_value(FsError::OutsideRoots { path: "/etc".to_string() }).unwrap();
+        assert_eq!(json, serde_json::json!({ "kind": "outside_roots", "path": "/etc" }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permission_denied_is_reported() {
+        use std::os::unix::fs::PermissionsExt;
+        let fixture = Fixture::new();
+        let locked = fixture.root.join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(&locked).is_ok() {
+            // Running as root, which ignores permissions
+            return;
+        }
+
+        let path = fixture.path("locked");
+        let result = fixture.browser().list(&path, 0, None);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(result, Err(FsError::PermissionDenied { path }));
+    }
+
+    #[test]
+    fn test_create_rename_and_delete() {
+        let fixture = Fixture::new();
+        let browser = fixture.browser();
+
+        let created = browser.create_directory(&fixture.path("Archive")).unwrap();
+        assert!(created.is_dir);
+        let renamed = browser.rename(&fixture.path("Archive"), &fixture.path("Old")).unwrap();
+        assert_eq!(renamed.name, "Old");
+        let taken = fixture.path("Documents");
+        assert_eq!(browser.rename(&fixture.path("Old"), &taken), Err(FsError::AlreadyExists { path: taken }));
+
+        browser.delete(&fixture.path("Old"), false).unwrap();
+        browser.delete(&fixture.path("Documents"), true).unwrap();
+        assert_eq!(fs::read_dir(&fixture.root).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_elevation_widens_the_roots() {
+        let fixture = Fixture::new();
+        let policy = FileTransferPolicy {
+            browse_roots: vec![fixture.root.clone()],
+            ..FileTransferPolicy::default()
+        };
+        let secret = fixture.outside.join("secret").display().to_string();
+        assert!(FileBrowser::new(&policy, false).stat(&secret).is_err());
+        assert_eq!(FileBrowser::new(&policy, true).stat(&secret).unwrap().size, 6);
+
+        // Without roots of its own an ordinary session sees nothing
+        let empty = FileTransferPolicy { browse_roots: Vec::new(), ..policy };
+        let listing = FileBrowser::new(&empty, false).list("", 0, None).unwrap();
+        assert_eq!(listing.total, 0);
+    }
+}
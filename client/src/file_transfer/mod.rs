@@ -0,0 +1,138 @@
+#![allow(dead_code)]
+
+use crate::error::{FileTransferError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub mod archive;
+pub mod browser;
+pub mod transfer;
+
+pub use browser::{FileBrowser, FsRequest};
+pub use transfer::{FileTransferManager, TransferProgress};
+
+/// Policy governing what the technician may pull from or push to this machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTransferPolicy {
+    /// Master switch for all file transfer operations
+    pub enabled: bool,
+    /// Largest single transfer (or archive) in bytes
+    pub max_transfer_size: u64,
+    /// Directories transfers are confined to; empty means unrestricted
+    pub allowed_roots: Vec<PathBuf>,
+    /// Where files sent by the technician are saved
+    #[serde(default = "default_download_dir")]
+    pub download_dir: PathBuf,
+    /// Where files the technician drops onto the screen are saved
+    #[serde(default = "default_drop_dir")]
+    pub drop_dir: PathBuf,
+    /// Whether the technician may drop files onto the screen; server policy
+    /// can switch it at runtime
+    #[serde(default = "default_true")]
+    pub drops_enabled: bool,
+    /// Tell the user when a dropped file has been saved
+    #[serde(default = "default_true")]
+    pub notify_drops: bool,
+    /// Size of each chunk of an outgoing transfer in bytes
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u32,
+    /// Upper bound for outgoing transfers in bytes per second; 0 is unlimited
+    #[serde(default)]
+    pub max_bandwidth: u64,
+    /// Directories the file browser shows to sessions without elevation
+    #[serde(default = "default_browse_roots")]
+    pub browse_roots: Vec<PathBuf>,
+    /// Directories the file browser shows to elevated sessions; empty means all
+    #[serde(default)]
+    pub elevated_browse_roots: Vec<PathBuf>,
+}
+
+fn default_download_dir() -> PathBuf {
+    dirs::download_dir()
+        .or_else(dirs::home_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("GhostLink")
+}
+
+/// The Desktop of the user the agent runs as
+fn default_drop_dir() -> PathBuf {
+    dirs::desktop_dir().unwrap_or_else(default_download_dir)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_chunk_size() -> u32 {
+    256 * 1024
+}
+
+/// The directory holding the user profiles, and this account's own home
+fn default_browse_roots() -> Vec<PathBuf> {
+    let profiles = if cfg!(windows) {
+        let drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+        PathBuf::from(format!("{}\\Users", drive))
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from("/Users")
+    } else {
+        PathBuf::from("/home")
+    };
+    let mut roots = vec![profiles];
+    roots.extend(dirs::home_dir().filter(|home| !home.starts_with(&roots[0])));
+    roots
+}
+
+impl Default for FileTransferPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_transfer_size: 1024 * 1024 * 1024, // 1 GiB
+            allowed_roots: Vec::new(),
+            download_dir: default_download_dir(),
+            drop_dir: default_drop_dir(),
+            drops_enabled: true,
+            notify_drops: true,
+            chunk_size: default_chunk_size(),
+            max_bandwidth: 0,
+            browse_roots: default_browse_roots(),
+            elevated_browse_roots: Vec::new(),
+        }
+    }
+}
+
+impl FileTransferPolicy {
+    /// Check that `path` may be transferred under this policy
+    pub fn check_path(&self, path: &Path) -> Result<()> {
+        if !self.enabled {
+            return Err(FileTransferError::Disabled { feature: "File transfer".to_string() }.into());
+        }
+        self.check_root(path)
+    }
+
+    /// Check that `path` lies under one of the allowed roots, if any are set
+    pub fn check_root(&self, path: &Path) -> Result<()> {
+        if self.allowed_roots.is_empty() {
+            return Ok(());
+        }
+        
+        let path = path.canonicalize()?;
+        let allowed = self.allowed_roots.iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| path.starts_with(root));
+        
+        if allowed {
+            Ok(())
+        } else {
+            Err(FileTransferError::OutsideRoots { path: path.display().to_string() }.into())
+        }
+    }
+
+    /// Effective size cap for a request, never exceeding the policy limit
+    pub fn effective_size_cap(&self, requested: u64) -> u64 {
+        if requested == 0 {
+            self.max_transfer_size
+        } else {
+            requested.min(self.max_transfer_size)
+        }
+    }
+}
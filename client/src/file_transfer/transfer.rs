@@ -0,0 +1,1117 @@
+//! Chunked, resumable file transfers between the agent and the technician
+//!
+//! A transfer is announced with `FileTransferStart` (name, size, chunk size
+//! and whole-file SHA-256) and followed by `FileTransfer` chunks. The
+//! receiver writes every chunk at its offset in a hidden part file inside the
+//! destination directory, so chunks may arrive in any order, and renames the
+//! file into place once all chunks are in and the checksum matches.
+//!
+//! After a reconnect the sender announces the transfer again; the receiver
+//! answers with `FileTransferResume` naming the first chunk it is missing and
+//! the sender continues from there.
+
+use crate::error::{ConnectionError, Context, FileTransferError, GhostLinkError, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use super::FileTransferPolicy;
+use crate::connection::RelayMessage;
+
+/// Largest chunk size a peer may announce
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Part files are hidden and named after the transfer
+const PART_PREFIX: &str = ".ghostlink-";
+const PART_SUFFIX: &str = ".part";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TransferKey {
+    session_id: String,
+    transfer_id: String,
+}
+
+impl TransferKey {
+    fn new(session_id: &str, transfer_id: &str) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            transfer_id: transfer_id.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    /// Technician to this machine
+    Incoming,
+    /// This machine to the technician
+    Outgoing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferState {
+    Active,
+    /// Waiting for the peer to say where to resume
+    Interrupted,
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+/// Progress of a transfer, published on every chunk and state change
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    pub session_id: String,
+    pub transfer_id: String,
+    pub filename: String,
+    pub direction: TransferDirection,
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+    pub bytes_per_sec: f64,
+    pub state: TransferState,
+    /// Dropped onto the technician's viewer, and saved to the drop directory
+    pub drop: bool,
+    /// Where an incoming file was saved, once it is
+    pub saved_to: Option<PathBuf>,
+}
+
+impl TransferProgress {
+    /// Completed fraction in `0.0..=1.0`
+    pub fn fraction(&self) -> f64 {
+        if self.total_bytes == 0 {
+            1.0
+        } else {
+            self.bytes_done as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+/// Length of chunk `index` of a file split into `chunk_size` pieces
+fn chunk_len(total_size: u64, chunk_size: u64, index: u32) -> u64 {
+    let offset = index as u64 * chunk_size;
+    total_size.saturating_sub(offset).min(chunk_size)
+}
+
+fn chunk_count(total_size: u64, chunk_size: u64) -> u32 {
+    total_size.div_ceil(chunk_size) as u32
+}
+
+fn rate(bytes: u64, since: Instant) -> f64 {
+    let elapsed = since.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+        bytes as f64 / elapsed
+    } else {
+        0.0
+    }
+}
+
+/// Accept a peer-supplied file name only if it is a single plain component
+pub fn sanitize_filename(name: &str) -> Result<&str> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+
+    if plain && !name.contains(['/', '\\', '\0']) {
+        Ok(name)
+    } else {
+        Err(GhostLinkError::Other(format!("Rejected file name: {:?}", name)))
+    }
+}
+
+/// SHA-256 of a file as lowercase hex
+pub async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// `dir/name`, or `dir/name (n).ext` if that is taken
+fn not_found(key: &TransferKey) -> GhostLinkError {
+    FileTransferError::NotFound { transfer_id: key.transfer_id.clone() }.into()
+}
+
+fn rejected(key: &TransferKey, reason: impl Into<String>) -> GhostLinkError {
+    FileTransferError::Rejected { transfer_id: key.transfer_id.clone(), reason: reason.into() }.into()
+}
+
+fn unique_destination(dir: &Path, name: &str) -> PathBuf {
+    let candidate = dir.join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let extension = path.extension().and_then(|s| s.to_str());
+    (1..)
+        .map(|n| match extension {
+            Some(ext) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap_or(candidate)
+}
+
+struct IncomingTransfer {
+    filename: String,
+    /// Directory the file is written to and finally saved in
+    dir: PathBuf,
+    drop: bool,
+    total_size: u64,
+    total_chunks: u32,
+    chunk_size: u64,
+    sha256: String,
+    part_path: PathBuf,
+    file: File,
+    received: BTreeSet<u32>,
+    bytes_done: u64,
+    started: Instant,
+}
+
+impl IncomingTransfer {
+    /// First chunk not yet received; the sender resumes from here
+    fn next_missing(&self) -> u32 {
+        (0..self.total_chunks)
+            .find(|index| !self.received.contains(index))
+            .unwrap_or(self.total_chunks)
+    }
+}
+
+struct OutgoingTransfer {
+    path: PathBuf,
+    filename: String,
+    total_size: u64,
+    total_chunks: u32,
+    chunk_size: u64,
+    sha256: String,
+    awaiting_resume: bool,
+    task: Option<JoinHandle<()>>,
+}
+
+/// Tracks all file transfers of this agent, keyed by session and transfer id
+pub struct FileTransferManager {
+    policy: FileTransferPolicy,
+    /// Starts as `policy.enabled`; server policy can switch it at runtime
+    enabled: AtomicBool,
+    /// Starts as `policy.drops_enabled`, switched the same way
+    drops_enabled: AtomicBool,
+    incoming: Mutex<HashMap<TransferKey, IncomingTransfer>>,
+    outgoing: Mutex<HashMap<TransferKey, OutgoingTransfer>>,
+    outbox: mpsc::Sender<RelayMessage>,
+    progress_tx: broadcast::Sender<TransferProgress>,
+}
+
+impl FileTransferManager {
+    /// Messages for the peer are queued on `outbox`
+    pub fn new(policy: FileTransferPolicy, outbox: mpsc::Sender<RelayMessage>) -> Self {
+        let (progress_tx, _) = broadcast::channel(256);
+        Self {
+            enabled: AtomicBool::new(policy.enabled),
+            drops_enabled: AtomicBool::new(policy.drops_enabled),
+            policy,
+            incoming: Mutex::new(HashMap::new()),
+            outgoing: Mutex::new(HashMap::new()),
+            outbox,
+            progress_tx,
+        }
+    }
+
+    /// Allow or refuse new transfers; transfers under way carry on
+    pub fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            info!("File transfer {}", if enabled { "enabled" } else { "disabled" });
+        }
+    }
+
+    /// Allow or refuse files dropped onto the technician's viewer
+    pub fn set_drops_enabled(&self, enabled: bool) {
+        if self.drops_enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            info!("File drops {}", if enabled { "enabled" } else { "disabled" });
+        }
+    }
+
+    fn ensure_enabled(&self) -> Result<()> {
+        if self.enabled.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(FileTransferError::Disabled { feature: "File transfer".to_string() }.into())
+        }
+    }
+
+    /// Observe transfer progress
+    pub fn subscribe(&self) -> broadcast::Receiver<TransferProgress> {
+        self.progress_tx.subscribe()
+    }
+
+    /// Handle a file transfer message from the peer
+    pub async fn handle_message(self: &Arc<Self>, message: RelayMessage) -> Result<()> {
+        match message {
+            RelayMessage::FileTransferStart {
+                session_id,
+                transfer_id,
+                filename,
+                total_size,
+                total_chunks,
+                chunk_size,
+                sha256,
+                drop: dropped,
+            } => {
+                let key = TransferKey::new(&session_id, &transfer_id);
+                let result = self
+                    .start_incoming(&key, &filename, total_size, total_chunks, chunk_size, &sha256, dropped)
+                    .await;
+                if let Err(e) = &result {
+                    self.reject_incoming(&key, &filename, dropped, e).await;
+                }
+                result
+            }
+            RelayMessage::FileTransfer {
+                session_id,
+                transfer_id,
+                file_data,
+                chunk_index,
+                ..
+            } => {
+                let key = TransferKey::new(&session_id, &transfer_id);
+                self.receive_chunk(&key, chunk_index, &file_data).await
+            }
+            RelayMessage::FileTransferResume { session_id, transfer_id, next_chunk } => {
+                let key = TransferKey::new(&session_id, &transfer_id);
+                self.resume_from(&key, next_chunk).await
+            }
+            RelayMessage::FileTransferComplete { session_id, transfer_id, success, error } => {
+                let key = TransferKey::new(&session_id, &transfer_id);
+                self.finish_outgoing(&key, success, error).await;
+                Ok(())
+            }
+            RelayMessage::FileTransferCancel { session_id, transfer_id, reason } => {
+                let key = TransferKey::new(&session_id, &transfer_id);
+                info!(
+                    "Peer cancelled transfer {}: {}",
+                    transfer_id,
+                    reason.as_deref().unwrap_or("no reason given")
+                );
+                self.drop_transfer(&key).await;
+                Ok(())
+            }
+            RelayMessage::FileTransferRequest { session_id, path } => {
+                self.send_file(&session_id, Path::new(&path)).await.map(|_| ())
+            }
+            other => Err(GhostLinkError::Other(format!("Not a file transfer message: {:?}", other))),
+        }
+    }
+
+    /// Start sending a file to the technician; returns the transfer id
+    pub async fn send_file(self: &Arc<Self>, session_id: &str, path: &Path) -> Result<String> {
+        self.ensure_enabled()?;
+        self.policy.check_root(path)?;
+        let metadata = fs::metadata(path)
+            .await
+            .with_context(|| format!("Cannot read {}", path.display()))?;
+        if !metadata.is_file() {
+            return Err(GhostLinkError::Other(format!("{} is not a file", path.display())));
+        }
+        if metadata.len() > self.policy.max_transfer_size {
+            return Err(GhostLinkError::Other(format!(
+                "{} exceeds the {} byte transfer limit",
+                path.display(),
+                self.policy.max_transfer_size
+            )));
+        }
+
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| GhostLinkError::Other(format!("Invalid file name: {}", path.display())))?
+            .to_string();
+        let chunk_size = self.policy.chunk_size.clamp(1, MAX_CHUNK_SIZE) as u64;
+        let transfer = OutgoingTransfer {
+            path: path.to_path_buf(),
+            total_size: metadata.len(),
+            total_chunks: chunk_count(metadata.len(), chunk_size),
+            chunk_size,
+            sha256: sha256_file(path).await?,
+            filename,
+            awaiting_resume: false,
+            task: None,
+        };
+
+        let transfer_id = Uuid::new_v4().to_string();
+        let key = TransferKey::new(session_id, &transfer_id);
+        info!("Sending {} ({} bytes) as transfer {}", path.display(), transfer.total_size, transfer_id);
+
+        self.outbox.send(Self::announcement(&key, &transfer)).await
+            .map_err(|_| ConnectionError::NotConnected)?;
+
+        let mut outgoing = self.outgoing.lock().await;
+        let transfer = outgoing.entry(key.clone()).or_insert(transfer);
+        transfer.task = Some(tokio::spawn(Arc::clone(self).send_chunks(key, 0)));
+
+        Ok(transfer_id)
+    }
+
+    /// Announce unfinished outgoing transfers again, e.g. after a reconnect.
+    /// Each continues once the peer reports which chunk it needs next.
+    pub async fn resume_all(&self) -> Result<()> {
+        let mut outgoing = self.outgoing.lock().await;
+        for (key, transfer) in outgoing.iter_mut() {
+            if let Some(task) = transfer.task.take() {
+                task.abort();
+            }
+            transfer.awaiting_resume = true;
+            debug!("Asking peer where to resume transfer {}", key.transfer_id);
+            self.outbox.send(Self::announcement(key, transfer)).await
+                .map_err(|_| ConnectionError::NotConnected)?;
+        }
+        Ok(())
+    }
+
+    /// Cancel a transfer in either direction and tell the peer
+    pub async fn cancel(&self, session_id: &str, transfer_id: &str) -> Result<()> {
+        let key = TransferKey::new(session_id, transfer_id);
+        if !self.drop_transfer(&key).await {
+            return Err(FileTransferError::NotFound { transfer_id: transfer_id.to_string() }.into());
+        }
+
+        self.outbox
+            .send(RelayMessage::FileTransferCancel {
+                session_id: session_id.to_string(),
+                transfer_id: transfer_id.to_string(),
+                reason: Some("Cancelled".to_string()),
+            })
+            .await
+            .map_err(|_| ConnectionError::NotConnected.into())
+    }
+
+    /// Drop every transfer of a session that ended
+    pub async fn cancel_session(&self, session_id: &str) {
+        let keys: Vec<TransferKey> = {
+            let incoming = self.incoming.lock().await;
+            let outgoing = self.outgoing.lock().await;
+            incoming.keys().chain(outgoing.keys())
+                .filter(|key| key.session_id == session_id)
+                .cloned()
+                .collect()
+        };
+
+        for key in keys {
+            self.drop_transfer(&key).await;
+        }
+    }
+
+    fn announcement(key: &TransferKey, transfer: &OutgoingTransfer) -> RelayMessage {
+        RelayMessage::FileTransferStart {
+            session_id: key.session_id.clone(),
+            transfer_id: key.transfer_id.clone(),
+            filename: transfer.filename.clone(),
+            total_size: transfer.total_size,
+            total_chunks: transfer.total_chunks,
+            chunk_size: transfer.chunk_size as u32,
+            sha256: transfer.sha256.clone(),
+            drop: false,
+        }
+    }
+
+    fn publish(&self, progress: TransferProgress) {
+        // No subscribers is fine
+        let _ = self.progress_tx.send(progress);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn start_incoming(
+        &self,
+        key: &TransferKey,
+        filename: &str,
+        total_size: u64,
+        total_chunks: u32,
+        chunk_size: u32,
+        sha256: &str,
+        dropped: bool,
+    ) -> Result<()> {
+        let mut incoming = self.incoming.lock().await;
+
+        // A repeated announcement means the sender reconnected
+        if let Some(transfer) = incoming.get(key) {
+            let next_chunk = transfer.next_missing();
+            info!("Resuming transfer {} at chunk {}", key.transfer_id, next_chunk);
+            return self.send_resume(key, next_chunk).await;
+        }
+
+        self.ensure_enabled()?;
+        if dropped && !self.drops_enabled.load(Ordering::Relaxed) {
+            return Err(FileTransferError::Disabled { feature: "File drops".to_string() }.into());
+        }
+        let filename = sanitize_filename(filename)?;
+        Uuid::parse_str(&key.transfer_id).context("Invalid transfer ID")?;
+        if total_size > self.policy.max_transfer_size {
+            return Err(rejected(key, format!(
+                "{} bytes exceeds the {} byte transfer limit",
+                total_size,
+                self.policy.max_transfer_size
+            )));
+        }
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(rejected(key, format!("Invalid chunk size: {}", chunk_size)));
+        }
+        if total_chunks != chunk_count(total_size, chunk_size as u64) {
+            return Err(rejected(key, format!("Chunk count {} does not match the file size", total_chunks)));
+        }
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(rejected(key, "Invalid SHA-256 checksum"));
+        }
+
+        let dir = if dropped { &self.policy.drop_dir } else { &self.policy.download_dir };
+        fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Cannot create {}", dir.display()))?;
+        let part_path = dir.join(format!("{}{}{}", PART_PREFIX, key.transfer_id, PART_SUFFIX));
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&part_path)
+            .await
+            .with_context(|| format!("Cannot create {}", part_path.display()))?;
+
+        info!("Receiving {} ({} bytes) as transfer {}", filename, total_size, key.transfer_id);
+        let transfer = IncomingTransfer {
+            filename: filename.to_string(),
+            dir: dir.clone(),
+            drop: dropped,
+            total_size,
+            total_chunks,
+            chunk_size: chunk_size as u64,
+            sha256: sha256.to_lowercase(),
+            part_path,
+            file,
+            received: BTreeSet::new(),
+            bytes_done: 0,
+            started: Instant::now(),
+        };
+
+        if total_chunks == 0 {
+            drop(incoming);
+            return self.finish_incoming(key, transfer).await;
+        }
+        incoming.insert(key.clone(), transfer);
+        drop(incoming);
+        self.send_resume(key, 0).await
+    }
+
+    async fn receive_chunk(&self, key: &TransferKey, index: u32, data: &[u8]) -> Result<()> {
+        let mut incoming = self.incoming.lock().await;
+        let transfer = incoming
+            .get_mut(key)
+            .ok_or_else(|| not_found(key))?;
+
+        if transfer.received.contains(&index) {
+            debug!("Duplicate chunk {} of transfer {}", index, key.transfer_id);
+            return Ok(());
+        }
+
+        let result = Self::write_chunk(transfer, index, data).await;
+        if let Err(e) = result {
+            if let Some(transfer) = incoming.remove(key) {
+                drop(incoming);
+                self.fail_incoming(key, transfer, &e).await;
+            }
+            return Err(e);
+        }
+
+        self.publish(TransferProgress {
+            session_id: key.session_id.clone(),
+            transfer_id: key.transfer_id.clone(),
+            filename: transfer.filename.clone(),
+            direction: TransferDirection::Incoming,
+            bytes_done: transfer.bytes_done,
+            total_bytes: transfer.total_size,
+            bytes_per_sec: rate(transfer.bytes_done, transfer.started),
+            state: TransferState::Active,
+            drop: transfer.drop,
+            saved_to: None,
+        });
+
+        if transfer.received.len() as u32 == transfer.total_chunks {
+            let transfer = incoming.remove(key).expect("transfer present");
+            drop(incoming);
+            return self.finish_incoming(key, transfer).await;
+        }
+        Ok(())
+    }
+
+    async fn write_chunk(transfer: &mut IncomingTransfer, index: u32, data: &[u8]) -> Result<()> {
+        if index >= transfer.total_chunks {
+            return Err(GhostLinkError::Other(format!("Chunk {} out of range", index)));
+        }
+        let expected = chunk_len(transfer.total_size, transfer.chunk_size, index);
+        if data.len() as u64 != expected {
+            return Err(GhostLinkError::Other(format!(
+                "Chunk {} has {} bytes, expected {}",
+                index,
+                data.len(),
+                expected
+            )));
+        }
+
+        transfer.file.seek(SeekFrom::Start(index as u64 * transfer.chunk_size)).await?;
+        transfer.file.write_all(data).await
+            .with_context(|| format!("Failed to write {}", transfer.filename))?;
+        transfer.received.insert(index);
+        transfer.bytes_done += expected;
+        Ok(())
+    }
+
+    async fn finish_incoming(&self, key: &TransferKey, transfer: IncomingTransfer) -> Result<()> {
+        let result = async {
+            transfer.file.sync_all().await
+                .with_context(|| format!("Failed to write {}", transfer.filename))?;
+
+            let actual = sha256_file(&transfer.part_path).await?;
+            if actual != transfer.sha256 {
+                debug!("Transfer {} expected SHA-256 {}, got {}", key.transfer_id, transfer.sha256, actual);
+                return Err(FileTransferError::ChecksumMismatch { filename: transfer.filename.clone() }.into());
+            }
+
+            let destination = unique_destination(&transfer.dir, &transfer.filename);
+            fs::rename(&transfer.part_path, &destination).await
+                .with_context(|| format!("Failed to move file to {}", destination.display()))?;
+            Ok(destination)
+        }
+        .await;
+
+        let destination = match result {
+            Ok(destination) => destination,
+            Err(e) => {
+                self.fail_incoming(key, transfer, &e).await;
+                return Err(e);
+            }
+        };
+
+        info!("Transfer {} saved to {}", key.transfer_id, destination.display());
+        self.publish(TransferProgress {
+            session_id: key.session_id.clone(),
+            transfer_id: key.transfer_id.clone(),
+            filename: transfer.filename.clone(),
+            direction: TransferDirection::Incoming,
+            bytes_done: transfer.total_size,
+            total_bytes: transfer.total_size,
+            bytes_per_sec: rate(transfer.bytes_done, transfer.started),
+            state: TransferState::Completed,
+            drop: transfer.drop,
+            saved_to: Some(destination),
+        });
+        self.send_complete(key, true, None).await
+    }
+
+    /// Remove the part file of a failed transfer and report the failure
+    async fn fail_incoming(&self, key: &TransferKey, transfer: IncomingTransfer, error: &GhostLinkError) {
+        warn!("Transfer {} failed: {:#}", key.transfer_id, error);
+        let IncomingTransfer { file, part_path, filename, bytes_done, total_size, started, drop: dropped, .. } = transfer;
+        drop(file);
+        if let Err(e) = fs::remove_file(&part_path).await {
+            warn!("Failed to remove {}: {}", part_path.display(), e);
+        }
+
+        self.publish(TransferProgress {
+            session_id: key.session_id.clone(),
+            transfer_id: key.transfer_id.clone(),
+            filename,
+            direction: TransferDirection::Incoming,
+            bytes_done,
+            total_bytes: total_size,
+            bytes_per_sec: rate(bytes_done, started),
+            state: TransferState::Failed(error.to_string()),
+            drop: dropped,
+            saved_to: None,
+        });
+        let _ = self.send_complete(key, false, Some(error.to_string())).await;
+    }
+
+    /// Report a transfer refused at announcement
+    async fn reject_incoming(&self, key: &TransferKey, filename: &str, dropped: bool, error: &GhostLinkError) {
+        warn!("Rejected transfer {}: {:#}", key.transfer_id, error);
+        self.publish(TransferProgress {
+            session_id: key.session_id.clone(),
+            transfer_id: key.transfer_id.clone(),
+            filename: filename.to_string(),
+            direction: TransferDirection::Incoming,
+            bytes_done: 0,
+            total_bytes: 0,
+            bytes_per_sec: 0.0,
+            state: TransferState::Failed(error.to_string()),
+            drop: dropped,
+            saved_to: None,
+        });
+        let _ = self.send_complete(key, false, Some(error.to_string())).await;
+    }
+
+    async fn send_resume(&self, key: &TransferKey, next_chunk: u32) -> Result<()> {
+        self.outbox
+            .send(RelayMessage::FileTransferResume {
+                session_id: key.session_id.clone(),
+                transfer_id: key.transfer_id.clone(),
+                next_chunk,
+            })
+            .await
+            .map_err(|_| ConnectionError::NotConnected.into())
+    }
+
+    async fn send_complete(&self, key: &TransferKey, success: bool, error: Option<String>) -> Result<()> {
+        self.outbox
+            .send(RelayMessage::FileTransferComplete {
+                session_id: key.session_id.clone(),
+                transfer_id: key.transfer_id.clone(),
+                success,
+                error,
+            })
+            .await
+            .map_err(|_| ConnectionError::NotConnected.into())
+    }
+
+    /// Restart a re-announced outgoing transfer where the peer wants it
+    async fn resume_from(self: &Arc<Self>, key: &TransferKey, next_chunk: u32) -> Result<()> {
+        let mut outgoing = self.outgoing.lock().await;
+        let Some(transfer) = outgoing.get_mut(key) else {
+            return Err(not_found(key));
+        };
+
+        // Without a re-announcement this only acknowledges the start
+        if !transfer.awaiting_resume {
+            return Ok(());
+        }
+        if next_chunk > transfer.total_chunks {
+            return Err(GhostLinkError::Other(format!("Resume point {} out of range", next_chunk)));
+        }
+
+        info!("Resuming transfer {} from chunk {}", key.transfer_id, next_chunk);
+        transfer.awaiting_resume = false;
+        transfer.task = Some(tokio::spawn(Arc::clone(self).send_chunks(key.clone(), next_chunk)));
+        Ok(())
+    }
+
+    async fn send_chunks(self: Arc<Self>, key: TransferKey, from_chunk: u32) {
+        if let Err(e) = self.stream_chunks(&key, from_chunk).await {
+            warn!("Transfer {} interrupted: {:#}", key.transfer_id, e);
+
+            let mut outgoing = self.outgoing.lock().await;
+            if let Some(transfer) = outgoing.get_mut(&key) {
+                transfer.awaiting_resume = true;
+                self.publish(TransferProgress {
+                    session_id: key.session_id.clone(),
+                    transfer_id: key.transfer_id.clone(),
+                    filename: transfer.filename.clone(),
+                    direction: TransferDirection::Outgoing,
+                    bytes_done: 0,
+                    total_bytes: transfer.total_size,
+                    bytes_per_sec: 0.0,
+                    state: TransferState::Interrupted,
+                    drop: false,
+                    saved_to: None,
+                });
+            }
+        }
+    }
+
+    async fn stream_chunks(&self, key: &TransferKey, from_chunk: u32) -> Result<()> {
+        let (path, filename, total_size, total_chunks, chunk_size) = {
+            let outgoing = self.outgoing.lock().await;
+            let transfer = outgoing
+                .get(key)
+                .ok_or_else(|| not_found(key))?;
+            (
+                transfer.path.clone(),
+                transfer.filename.clone(),
+                transfer.total_size,
+                transfer.total_chunks,
+                transfer.chunk_size,
+            )
+        };
+
+        let mut file = File::open(&path).await
+            .with_context(|| format!("Cannot open {}", path.display()))?;
+        let offset = from_chunk as u64 * chunk_size;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let started = Instant::now();
+        let mut sent = 0u64;
+        let mut buffer = vec![0u8; chunk_size as usize];
+
+        for index in from_chunk..total_chunks {
+            let len = chunk_len(total_size, chunk_size, index) as usize;
+            file.read_exact(&mut buffer[..len]).await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+
+            self.outbox
+                .send(RelayMessage::FileTransfer {
+                    session_id: key.session_id.clone(),
+                    transfer_id: key.transfer_id.clone(),
+                    file_data: buffer[..len].to_vec(),
+                    filename: filename.clone(),
+                    total_size,
+                    chunk_index: index,
+                    total_chunks,
+                })
+                .await
+                .map_err(|_| ConnectionError::NotConnected)?;
+
+            sent += len as u64;
+            self.throttle(started, sent).await;
+            self.publish(TransferProgress {
+                session_id: key.session_id.clone(),
+                transfer_id: key.transfer_id.clone(),
+                filename: filename.clone(),
+                direction: TransferDirection::Outgoing,
+                bytes_done: offset + sent,
+                total_bytes: total_size,
+                bytes_per_sec: rate(sent, started),
+                state: TransferState::Active,
+                drop: false,
+                saved_to: None,
+            });
+        }
+
+        debug!("Transfer {} sent, waiting for confirmation", key.transfer_id);
+        Ok(())
+    }
+
+    /// Keep the average rate at or below the bandwidth limit
+    async fn throttle(&self, started: Instant, sent: u64) {
+        if self.policy.max_bandwidth == 0 {
+            return;
+        }
+
+        let due = Duration::from_secs_f64(sent as f64 / self.policy.max_bandwidth as f64);
+        let elapsed = started.elapsed();
+        if due > elapsed {
+            tokio::time::sleep(due - elapsed).await;
+        }
+    }
+
+    async fn finish_outgoing(&self, key: &TransferKey, success: bool, error: Option<String>) {
+        let Some(transfer) = self.outgoing.lock().await.remove(key) else {
+            debug!("Completion for unknown transfer {}", key.transfer_id);
+            return;
+        };
+        if let Some(task) = transfer.task {
+            task.abort();
+        }
+
+        let state = if success {
+            info!("Transfer {} of {} confirmed by peer", key.transfer_id, transfer.filename);
+            TransferState::Completed
+        } else {
+            let error = error.unwrap_or_else(|| "unknown error".to_string());
+            warn!("Peer rejected transfer {}: {}", key.transfer_id, error);
+            TransferState::Failed(error)
+        };
+        self.publish(TransferProgress {
+            session_id: key.session_id.clone(),
+            transfer_id: key.transfer_id.clone(),
+            filename: transfer.filename,
+            direction: TransferDirection::Outgoing,
+            bytes_done: if success { transfer.total_size } else { 0 },
+            total_bytes: transfer.total_size,
+            bytes_per_sec: 0.0,
+            state,
+            drop: false,
+            saved_to: None,
+        });
+    }
+
+    /// Forget a transfer, stopping its sender or deleting its part file.
+    /// Returns false if it was not known.
+    async fn drop_transfer(&self, key: &TransferKey) -> bool {
+        let incoming = self.incoming.lock().await.remove(key);
+        if let Some(transfer) = incoming {
+            let IncomingTransfer { file, part_path, filename, bytes_done, total_size, drop: dropped, .. } = transfer;
+            drop(file);
+            if let Err(e) = fs::remove_file(&part_path).await {
+                warn!("Failed to remove {}: {}", part_path.display(), e);
+            }
+            self.publish_cancelled(key, filename, TransferDirection::Incoming, dropped, bytes_done, total_size);
+            return true;
+        }
+
+        let outgoing = self.outgoing.lock().await.remove(key);
+        if let Some(transfer) = outgoing {
+            if let Some(task) = transfer.task {
+                task.abort();
+            }
+            self.publish_cancelled(key, transfer.filename, TransferDirection::Outgoing, false, 0, transfer.total_size);
+            return true;
+        }
+
+        false
+    }
+
+    fn publish_cancelled(
+        &self,
+        key: &TransferKey,
+        filename: String,
+        direction: TransferDirection,
+        dropped: bool,
+        bytes_done: u64,
+        total_bytes: u64,
+    ) {
+        info!("Transfer {} cancelled", key.transfer_id);
+        self.publish(TransferProgress {
+            session_id: key.session_id.clone(),
+            transfer_id: key.transfer_id.clone(),
+            filename,
+            direction,
+            bytes_done,
+            total_bytes,
+            bytes_per_sec: 0.0,
+            state: TransferState::Cancelled,
+            drop: dropped,
+            saved_to: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SESSION: &str = "session-1";
+
+    fn manager(download_dir: &Path, chunk_size: u32) -> (Arc<FileTransferManager>, mpsc::Receiver<RelayMessage>) {
+        let policy = FileTransferPolicy {
+            download_dir: download_dir.to_path_buf(),
+            chunk_size,
+            ..FileTransferPolicy::default()
+        };
+        let (outbox, rx) = mpsc::channel(256);
+        (Arc::new(FileTransferManager::new(policy, outbox)), rx)
+    }
+
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.file_name().to_string_lossy().into_owned()).collect())
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    fn start(transfer_id: &str, filename: &str, data: &[u8], chunk_size: u32, sha256: String) -> RelayMessage {
+        RelayMessage::FileTransferStart {
+            session_id: SESSION.to_string(),
+            transfer_id: transfer_id.to_string(),
+            filename: filename.to_string(),
+            total_size: data.len() as u64,
+            total_chunks: chunk_count(data.len() as u64, chunk_size as u64),
+            chunk_size,
+            sha256,
+            drop: false,
+        }
+    }
+
+    fn chunk(transfer_id: &str, data: &[u8], chunk_size: usize, index: u32) -> RelayMessage {
+        let begin = index as usize * chunk_size;
+        let end = (begin + chunk_size).min(data.len());
+        RelayMessage::FileTransfer {
+            session_id: SESSION.to_string(),
+            transfer_id: transfer_id.to_string(),
+            file_data: data[begin..end].to_vec(),
+            filename: String::new(),
+            total_size: data.len() as u64,
+            chunk_index: index,
+            total_chunks: chunk_count(data.len() as u64, chunk_size as u64),
+        }
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    fn completion(rx: &mut mpsc::Receiver<RelayMessage>) -> Option<(bool, Option<String>)> {
+        while let Ok(message) = rx.try_recv() {
+            if let RelayMessage::FileTransferComplete { success, error, .. } = message {
+                return Some((success, error));
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_multi_chunk_round_trip() {
+        let source = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let path = source.path().join("report.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let (sender, mut sender_rx) = manager(source.path(), 1024);
+        let (receiver, mut receiver_rx) = manager(target.path(), 1024);
+        let mut progress = sender.subscribe();
+
+        sender.send_file(SESSION, &path).await.unwrap();
+
+        let confirmed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    Some(message) = sender_rx.recv() => receiver.handle_message(message).await.unwrap(),
+                    Some(message) = receiver_rx.recv() => sender.handle_message(message).await.unwrap(),
+                    Ok(update) = progress.recv() => {
+                        if update.state == TransferState::Completed {
+                            return update;
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(confirmed.bytes_done, data.len() as u64);
+        assert_eq!(dir_entries(target.path()), vec!["report.bin"]);
+        assert_eq!(std::fs::read(target.path().join("report.bin")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_and_resume() {
+        let target = TempDir::new().unwrap();
+        let (receiver, mut rx) = manager(target.path(), 4);
+        let id = Uuid::new_v4().to_string();
+        let data = b"chunks arrive in any order".to_vec();
+        let total = chunk_count(data.len() as u64, 4);
+
+        receiver.handle_message(start(&id, "notes.txt", &data, 4, sha256(&data))).await.unwrap();
+        for index in [1, 0, 3] {
+            receiver.handle_message(chunk(&id, &data, 4, index)).await.unwrap();
+        }
+
+        // A reconnecting sender learns the first missing chunk
+        while rx.try_recv().is_ok() {}
+        receiver.handle_message(start(&id, "notes.txt", &data, 4, sha256(&data))).await.unwrap();
+        match rx.try_recv().unwrap() {
+            RelayMessage::FileTransferResume { next_chunk, .. } => assert_eq!(next_chunk, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        for index in (2..total).rev() {
+            receiver.handle_message(chunk(&id, &data, 4, index)).await.unwrap();
+        }
+        assert_eq!(completion(&mut rx), Some((true, None)));
+        assert_eq!(std::fs::read(target.path().join("notes.txt")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch_leaves_nothing() {
+        let target = TempDir::new().unwrap();
+        let (receiver, mut rx) = manager(target.path(), 8);
+        let id = Uuid::new_v4().to_string();
+        let data = b"tampered in transit".to_vec();
+
+        receiver.handle_message(start(&id, "setup.exe", &data, 8, sha256(b"original"))).await.unwrap();
+        receiver.handle_message(chunk(&id, &data, 8, 0)).await.unwrap();
+        receiver.handle_message(chunk(&id, &data, 8, 1)).await.unwrap();
+        let err = receiver.handle_message(chunk(&id, &data, 8, 2)).await.unwrap_err();
+
+        assert!(
+            matches!(&err, GhostLinkError::FileTransfer(FileTransferError::ChecksumMismatch { filename }) if filename == "setup.exe"),
+            "{}",
+            err
+        );
+        assert!(matches!(completion(&mut rx), Some((false, Some(_)))));
+        assert!(dir_entries(target.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drops_land_in_the_drop_dir_unless_disabled() {
+        let downloads = TempDir::new().unwrap();
+        let desktop = TempDir::new().unwrap();
+        let policy = FileTransferPolicy {
+            download_dir: downloads.path().to_path_buf(),
+            drop_dir: desktop.path().to_path_buf(),
+            ..FileTransferPolicy::default()
+        };
+        let (outbox, mut rx) = mpsc::channel(256);
+        let receiver = Arc::new(FileTransferManager::new(policy, outbox));
+        let mut progress = receiver.subscribe();
+        let data = b"dropped onto the viewer".to_vec();
+        let drop_start = |id: &str| match start(id, "notes.txt", &data, 8, sha256(&data)) {
+            RelayMessage::FileTransferStart { session_id, transfer_id, filename, total_size, total_chunks, chunk_size, sha256, .. } => {
+                RelayMessage::FileTransferStart { session_id, transfer_id, filename, total_size, total_chunks, chunk_size, sha256, drop: true }
+            }
+            other => other,
+        };
+
+        let id = Uuid::new_v4().to_string();
+        receiver.handle_message(drop_start(&id)).await.unwrap();
+        for index in 0..chunk_count(data.len() as u64, 8) {
+            receiver.handle_message(chunk(&id, &data, 8, index)).await.unwrap();
+        }
+        assert_eq!(completion(&mut rx), Some((true, None)));
+        assert_eq!(dir_entries(desktop.path()), vec!["notes.txt"]);
+        assert!(dir_entries(downloads.path()).is_empty());
+
+        let saved = std::iter::from_fn(|| progress.try_recv().ok())
+            .find(|update| update.state == TransferState::Completed)
+            .unwrap();
+        assert!(saved.drop);
+        assert_eq!(saved.saved_to, Some(desktop.path().join("notes.txt")));
+
+        // Policy can refuse drops while other transfers carry on
+        receiver.set_drops_enabled(false);
+        let refused = receiver.handle_message(drop_start(&Uuid::new_v4().to_string())).await.unwrap_err();
+        assert!(
+            matches!(&refused, GhostLinkError::FileTransfer(FileTransferError::Disabled { feature }) if feature == "File drops"),
+            "{}",
+            refused
+        );
+        assert!(matches!(completion(&mut rx), Some((false, Some(_)))));
+        let id = Uuid::new_v4().to_string();
+        receiver.handle_message(start(&id, "notes.txt", &data, 8, sha256(&data))).await.unwrap();
+        assert_eq!(dir_entries(desktop.path()), vec!["notes.txt"]);
+        assert_eq!(dir_entries(downloads.path()).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_path_traversal_and_cleans_up_on_cancel() {
+        let target = TempDir::new().unwrap();
+        let (receiver, mut rx) = manager(target.path(), 8);
+        let data = b"payload".to_vec();
+
+        for name in ["../escape.txt", "..", "dir/file.txt", "..\\file.txt", "/etc/passwd", ""] {
+            let id = Uuid::new_v4().to_string();
+            assert!(receiver.handle_message(start(&id, name, &data, 8, sha256(&data))).await.is_err(), "{:?}", name);
+        }
+        assert!(dir_entries(target.path()).is_empty());
+
+        let id = Uuid::new_v4().to_string();
+        let data = vec![7u8; 64];
+        receiver.handle_message(start(&id, "big.bin", &data, 8, sha256(&data))).await.unwrap();
+        receiver.handle_message(chunk(&id, &data, 8, 0)).await.unwrap();
+        assert_eq!(dir_entries(target.path()).len(), 1);
+
+        while rx.try_recv().is_ok() {}
+        receiver.cancel(SESSION, &id).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(RelayMessage::FileTransferCancel { .. })));
+        assert!(dir_entries(target.path()).is_empty());
+        assert!(receiver.handle_message(chunk(&id, &data, 8, 1)).await.is_err());
+    }
+}
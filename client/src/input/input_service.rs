@@ -19,7 +19,7 @@ use tracing::{debug, error, info, trace, warn};
 use parking_lot::RwLock as ParkingRwLock;
 
 const INPUT_PROCESSING_INTERVAL_MS: u64 = 1; // Process input every 1ms for low latency
-const MAX_EVENTS_PER_BATCH: usize = 50;      // Process up to 50 events per batch
+const MAX_EVENTS_PER_BATCH: usize = 50;      // Process up to 50 events per tick, whole batches only
 const INPUT_STATS_INTERVAL_SECONDS: u64 = 30; // Log stats every 30 seconds
 
 /// High-performance input service for processing remote control events
@@ -29,10 +29,10 @@ pub struct InputService {
     /// X11 input injector for native input
     #[cfg(target_os = "linux")]
     injector: Arc<RwLock<X11InputInjector>>,
-    /// Input event receiver channel
-    event_receiver: Arc<RwLock<Option<mpsc::Receiver<InputEvent>>>>,
-    /// Input event sender (for external use)
-    event_sender: mpsc::Sender<InputEvent>,
+    /// Input batch receiver channel
+    event_receiver: Arc<RwLock<Option<mpsc::Receiver<Vec<InputEvent>>>>>,
+    /// Input batch sender (for external use)
+    event_sender: mpsc::Sender<Vec<InputEvent>>,
     /// Service running state
     is_running: Arc<AtomicBool>,
     /// Input processing task handle
@@ -164,24 +164,34 @@ impl InputService {
     
     /// Send input event to be processed
     pub async fn send_input_event(&self, event: InputEvent) -> Result<()> {
+        self.send_input_batch(vec![event]).await
+    }
+    
+    /// Send a batch of input events to be applied together, in order.
+    /// The batch is queued whole or not at all, so a press is never
+    /// applied without the release that came with it.
+    pub async fn send_input_batch(&self, events: Vec<InputEvent>) -> Result<()> {
         if !self.is_running.load(Ordering::Relaxed) {
             return Err(GhostLinkError::Other("Input service not running".to_string()));
         }
+        if events.is_empty() {
+            return Ok(());
+        }
         
-        // Validate event if enabled
+        // Validate every event before any of them is queued
         if self.config.validate_events {
-            event.validate()?;
+            events.iter().try_for_each(InputEvent::validate)?;
         }
         
         // Check rate limiting
         if !self.check_rate_limit().await {
-            warn!("Input event rate limit exceeded, dropping event");
+            warn!("Input event rate limit exceeded, dropping batch of {} events", events.len());
             return Err(GhostLinkError::Other("Rate limit exceeded".to_string()));
         }
         
-        // Send event to processing queue
-        self.event_sender.send(event).await
-            .map_err(|e| GhostLinkError::Other(format!("Failed to queue input event: {}", e)))?;
+        // Send batch to processing queue
+        self.event_sender.send(events).await
+            .map_err(|e| GhostLinkError::Other(format!("Failed to queue input batch: {}", e)))?;
         
         Ok(())
     }
@@ -192,8 +202,15 @@ impl InputService {
         self.send_input_event(event).await
     }
     
+    /// Process a JSON array of input events as one batch
+    pub async fn process_input_batch_json(&self, json: &str) -> Result<()> {
+        let events = serde_json::from_str(json)
+            .map_err(|e| GhostLinkError::Other(format!("Failed to deserialize input batch: {}", e)))?;
+        self.send_input_batch(events).await
+    }
+    
     /// Spawn the main input processing task
-    async fn spawn_processing_task(&self, mut receiver: mpsc::Receiver<InputEvent>) -> JoinHandle<()> {
+    async fn spawn_processing_task(&self, mut receiver: mpsc::Receiver<Vec<InputEvent>>) -> JoinHandle<()> {
         let injector = Arc::clone(&self.injector);
         let stats = Arc::clone(&self.stats);
         let is_running = Arc::clone(&self.is_running);
@@ -207,16 +224,20 @@ impl InputService {
             interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
             
             let mut stats_timer = Instant::now();
-            let mut event_batch = Vec::with_capacity(MAX_EVENTS_PER_BATCH);
+            let mut pending: Vec<Vec<InputEvent>> = Vec::new();
             
             while is_running.load(Ordering::Relaxed) {
                 interval.tick().await;
                 
-                // Collect events from the channel (non-blocking)
-                event_batch.clear();
-                while event_batch.len() < MAX_EVENTS_PER_BATCH {
+                // Collect whole batches from the channel (non-blocking)
+                pending.clear();
+                let mut queued = 0;
+                while queued < MAX_EVENTS_PER_BATCH {
                     match receiver.try_recv() {
-                        Ok(event) => event_batch.push(event),
+                        Ok(batch) => {
+                            queued += batch.len();
+                            pending.push(batch);
+                        }
                         Err(mpsc::error::TryRecvError::Empty) => break,
                         Err(mpsc::error::TryRecvError::Disconnected) => {
                             info!("Input event channel disconnected");
@@ -225,11 +246,13 @@ impl InputService {
                     }
                 }
                 
-                // Process the batch of events
-                if !event_batch.is_empty() {
-                    trace!("Processing {} input events", event_batch.len());
+                // Apply each batch in order, holding the injector so nothing
+                // else lands in the middle of one
+                for batch in &pending {
+                    trace!("Processing batch of {} input events", batch.len());
+                    let mut inj = injector.write().await;
                     
-                    for event in &event_batch {
+                    for event in batch {
                         stats.write().record_event(event);
                         
                         if config.log_events {
@@ -237,7 +260,7 @@ impl InputService {
                         }
                         
                         // Process the event
-                        match Self::process_single_event(&injector, event, &config).await {
+                        match Self::process_single_event(&mut inj, event, &config) {
                             Ok(()) => {
                                 stats.write().record_success();
                                 trace!("Successfully processed {} event", event.event_type());
@@ -262,8 +285,8 @@ impl InputService {
     }
     
     /// Process a single input event
-    async fn process_single_event(
-        injector: &Arc<RwLock<X11InputInjector>>,
+    fn process_single_event(
+        inj: &mut X11InputInjector,
         event: &InputEvent,
         config: &InputServiceConfig,
     ) -> Result<()> {
@@ -271,33 +294,28 @@ impl InputService {
             // Mouse events
             InputEvent::MouseMove { x, y, .. } => {
                 if !config.enable_mouse { return Ok(()); }
-                let mut inj = injector.write().await;
                 inj.move_mouse_absolute(*x, *y)?;
             }
             
             InputEvent::MouseMoveRelative { dx, dy, .. } => {
                 if !config.enable_mouse { return Ok(()); }
-                let mut inj = injector.write().await;
                 inj.move_mouse_relative(*dx, *dy)?;
             }
             
             InputEvent::MousePress { button, x, y, .. } => {
                 if !config.enable_mouse { return Ok(()); }
-                let mut inj = injector.write().await;
                 inj.move_mouse_absolute(*x, *y)?;
                 inj.press_mouse_button((*button).into())?;
             }
             
             InputEvent::MouseRelease { button, x, y, .. } => {
                 if !config.enable_mouse { return Ok(()); }
-                let mut inj = injector.write().await;
                 inj.move_mouse_absolute(*x, *y)?;
                 inj.release_mouse_button((*button).into())?;
             }
             
             InputEvent::MouseClick { button, x, y, double_click, .. } => {
                 if !config.enable_mouse { return Ok(()); }
-                let mut inj = injector.write().await;
                 inj.move_mouse_absolute(*x, *y)?;
                 
                 if *double_click {
@@ -309,7 +327,6 @@ impl InputService {
             
             InputEvent::MouseScroll { direction, clicks, x, y, .. } => {
                 if !config.enable_mouse { return Ok(()); }
-                let mut inj = injector.write().await;
                 inj.move_mouse_absolute(*x, *y)?;
                 inj.scroll_mouse((*direction).into(), *clicks)?;
             }
@@ -317,31 +334,26 @@ impl InputService {
             // Keyboard events
             InputEvent::KeyPress { key, modifiers, .. } => {
                 if !config.enable_keyboard { return Ok(()); }
-                let inj = injector.read().await;
-                Self::process_key_press(&*inj, key, *modifiers)?;
+                Self::process_key_press(inj, key, *modifiers)?;
             }
             
             InputEvent::KeyRelease { key, modifiers, .. } => {
                 if !config.enable_keyboard { return Ok(()); }
-                let inj = injector.read().await;
-                Self::process_key_release(&*inj, key, *modifiers)?;
+                Self::process_key_release(inj, key, *modifiers)?;
             }
             
             InputEvent::KeyStroke { key, modifiers, .. } => {
                 if !config.enable_keyboard { return Ok(()); }
-                let inj = injector.read().await;
-                Self::process_key_stroke(&*inj, key, *modifiers)?;
+                Self::process_key_stroke(inj, key, *modifiers)?;
             }
             
             InputEvent::TypeText { text, .. } => {
                 if !config.enable_keyboard { return Ok(()); }
-                let inj = injector.read().await;
                 inj.type_string(text)?;
             }
             
             InputEvent::KeyCombination { keys, .. } => {
                 if !config.enable_keyboard { return Ok(()); }
-                let inj = injector.read().await;
                 let converted_keys: Vec<_> = keys.iter().map(|k| (*k).into()).collect();
                 inj.send_key_combination(&converted_keys)?;
             }
@@ -488,8 +500,8 @@ impl InputService {
         self.is_running.load(Ordering::Relaxed)
     }
     
-    /// Get event sender for external use
-    pub fn get_event_sender(&self) -> mpsc::Sender<InputEvent> {
+    /// Get batch sender for external use
+    pub fn get_event_sender(&self) -> mpsc::Sender<Vec<InputEvent>> {
         self.event_sender.clone()
     }
     
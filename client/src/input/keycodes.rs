@@ -0,0 +1,356 @@
+//! Key code tables for every platform
+//!
+//! One row per [`KeyCode`] with its X11 keysym, Windows virtual-key code and
+//! set-1 scancode, macOS virtual key code (`CGKeyCode`) and Linux evdev code.
+//! Lookups in either direction are scans of [`KEY_TABLE`]; the table is small
+//! enough that this costs nothing next to injecting the event.
+//!
+//! `KeyCode` names keys by their US-layout legend. How that interacts with the
+//! remote user's layout depends on the column:
+//!
+//! - X11 keysyms and Windows virtual-key codes are layout-aware: the X server
+//!   or Windows finds whichever physical key produces the symbol in the active
+//!   layout, so `KeyCode::Z` types "z" on a German keyboard too. These are
+//!   what handlers should inject.
+//! - Windows scancodes, macOS key codes and evdev codes are positional: they
+//!   name a physical key, and what it types depends on the layout. Use them
+//!   only where there is no layout-aware API (macOS `CGEventCreateKeyboardEvent`,
+//!   uinput) or when an application reads raw scancodes. Text should go
+//!   through the platform's Unicode path instead (`KEYEVENTF_UNICODE`,
+//!   `CGEventKeyboardSetUnicodeString`, keysyms).
+//!
+//! Extended Windows scancodes carry their 0xE0 (or 0xE1 for Pause) prefix in
+//! the high byte. Mac keyboards have no Insert, Num Lock, Scroll Lock, Print
+//! Screen or Pause keys; those map to Help, Clear, F14, F13 and F15, the keys
+//! in the same positions on Apple extended keyboards.
+
+use super::KeyCode;
+
+/// Native codes for one key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMapping {
+    pub key: KeyCode,
+    pub x11_keysym: u32,
+    pub windows_vk: u16,
+    pub windows_scancode: u16,
+    pub macos_keycode: u16,
+    pub evdev: u16,
+}
+
+macro_rules! keys {
+    ($($key:ident => $x11:expr, $vk:expr, $scan:expr, $mac:expr, $evdev:expr;)*) => {
+        &[$(KeyMapping {
+            key: KeyCode::$key,
+            x11_keysym: $x11,
+            windows_vk: $vk,
+            windows_scancode: $scan,
+            macos_keycode: $mac,
+            evdev: $evdev,
+        },)*]
+    };
+}
+
+/// Every fixed key. `KeyCode::Raw` is the only variant without a row.
+pub static KEY_TABLE: &[KeyMapping] = keys! {
+    //             X11 keysym  VK    scancode  macOS  evdev
+    A =>           0x0061,     0x41, 0x001e,   0x00,  30;
+    B =>           0x0062,     0x42, 0x0030,   0x0b,  48;
+    C =>           0x0063,     0x43, 0x002e,   0x08,  46;
+    D =>           0x0064,     0x44, 0x0020,   0x02,  32;
+    E =>           0x0065,     0x45, 0x0012,   0x0e,  18;
+    F =>           0x0066,     0x46, 0x0021,   0x03,  33;
+    G =>           0x0067,     0x47, 0x0022,   0x05,  34;
+    H =>           0x0068,     0x48, 0x0023,   0x04,  35;
+    I =>           0x0069,     0x49, 0x0017,   0x22,  23;
+    J =>           0x006a,     0x4a, 0x0024,   0x26,  36;
+    K =>           0x006b,     0x4b, 0x0025,   0x28,  37;
+    L =>           0x006c,     0x4c, 0x0026,   0x25,  38;
+    M =>           0x006d,     0x4d, 0x0032,   0x2e,  50;
+    N =>           0x006e,     0x4e, 0x0031,   0x2d,  49;
+    O =>           0x006f,     0x4f, 0x0018,   0x1f,  24;
+    P =>           0x0070,     0x50, 0x0019,   0x23,  25;
+    Q =>           0x0071,     0x51, 0x0010,   0x0c,  16;
+    R =>           0x0072,     0x52, 0x0013,   0x0f,  19;
+    S =>           0x0073,     0x53, 0x001f,   0x01,  31;
+    T =>           0x0074,     0x54, 0x0014,   0x11,  20;
+    U =>           0x0075,     0x55, 0x0016,   0x20,  22;
+    V =>           0x0076,     0x56, 0x002f,   0x09,  47;
+    W =>           0x0077,     0x57, 0x0011,   0x0d,  17;
+    X =>           0x0078,     0x58, 0x002d,   0x07,  45;
+    Y =>           0x0079,     0x59, 0x0015,   0x10,  21;
+    Z =>           0x007a,     0x5a, 0x002c,   0x06,  44;
+
+    Key0 =>        0x0030,     0x30, 0x000b,   0x1d,  11;
+    Key1 =>        0x0031,     0x31, 0x0002,   0x12,  2;
+    Key2 =>        0x0032,     0x32, 0x0003,   0x13,  3;
+    Key3 =>        0x0033,     0x33, 0x0004,   0x14,  4;
+    Key4 =>        0x0034,     0x34, 0x0005,   0x15,  5;
+    Key5 =>        0x0035,     0x35, 0x0006,   0x17,  6;
+    Key6 =>        0x0036,     0x36, 0x0007,   0x16,  7;
+    Key7 =>        0x0037,     0x37, 0x0008,   0x1a,  8;
+    Key8 =>        0x0038,     0x38, 0x0009,   0x1c,  9;
+    Key9 =>        0x0039,     0x39, 0x000a,   0x19,  10;
+
+    F1 =>          0xffbe,     0x70, 0x003b,   0x7a,  59;
+    F2 =>          0xffbf,     0x71, 0x003c,   0x78,  60;
+    F3 =>          0xffc0,     0x72, 0x003d,   0x63,  61;
+    F4 =>          0xffc1,     0x73, 0x003e,   0x76,  62;
+    F5 =>          0xffc2,     0x74, 0x003f,   0x60,  63;
+    F6 =>          0xffc3,     0x75, 0x0040,   0x61,  64;
+    F7 =>          0xffc4,     0x76, 0x0041,   0x62,  65;
+    F8 =>          0xffc5,     0x77, 0x0042,   0x64,  66;
+    F9 =>          0xffc6,     0x78, 0x0043,   0x65,  67;
+    F10 =>         0xffc7,     0x79, 0x0044,   0x6d,  68;
+    F11 =>         0xffc8,     0x7a, 0x0057,   0x67,  87;
+    F12 =>         0xffc9,     0x7b, 0x0058,   0x6f,  88;
+
+    // Left-hand modifiers; Super is the Windows key / Command
+    Shift =>       0xffe1,     0xa0, 0x002a,   0x38,  42;
+    Ctrl =>        0xffe3,     0xa2, 0x001d,   0x3b,  29;
+    Alt =>         0xffe9,     0xa4, 0x0038,   0x3a,  56;
+    Super =>       0xffeb,     0x5b, 0xe05b,   0x37,  125;
+
+    Up =>          0xff52,     0x26, 0xe048,   0x7e,  103;
+    Down =>        0xff54,     0x28, 0xe050,   0x7d,  108;
+    Left =>        0xff51,     0x25, 0xe04b,   0x7b,  105;
+    Right =>       0xff53,     0x27, 0xe04d,   0x7c,  106;
+    Home =>        0xff50,     0x24, 0xe047,   0x73,  102;
+    End =>         0xff57,     0x23, 0xe04f,   0x77,  107;
+    PageUp =>      0xff55,     0x21, 0xe049,   0x74,  104;
+    PageDown =>    0xff56,     0x22, 0xe051,   0x79,  109;
+
+    Space =>       0x0020,     0x20, 0x0039,   0x31,  57;
+    Enter =>       0xff0d,     0x0d, 0x001c,   0x24,  28;
+    Tab =>         0xff09,     0x09, 0x000f,   0x30,  15;
+    Backspace =>   0xff08,     0x08, 0x000e,   0x33,  14;
+    Delete =>      0xffff,     0x2e, 0xe053,   0x75,  111;
+    Escape =>      0xff1b,     0x1b, 0x0001,   0x35,  1;
+
+    Numpad0 =>     0xffb0,     0x60, 0x0052,   0x52,  82;
+    Numpad1 =>     0xffb1,     0x61, 0x004f,   0x53,  79;
+    Numpad2 =>     0xffb2,     0x62, 0x0050,   0x54,  80;
+    Numpad3 =>     0xffb3,     0x63, 0x0051,   0x55,  81;
+    Numpad4 =>     0xffb4,     0x64, 0x004b,   0x56,  75;
+    Numpad5 =>     0xffb5,     0x65, 0x004c,   0x57,  76;
+    Numpad6 =>     0xffb6,     0x66, 0x004d,   0x58,  77;
+    Numpad7 =>     0xffb7,     0x67, 0x0047,   0x59,  71;
+    Numpad8 =>     0xffb8,     0x68, 0x0048,   0x5b,  72;
+    Numpad9 =>     0xffb9,     0x69, 0x0049,   0x5c,  73;
+    // Windows has no separate VK for keypad Enter; the extended scancode
+    // tells it apart from Enter
+    NumpadEnter => 0xff8d,     0x0d, 0xe01c,   0x4c,  96;
+    NumpadPlus =>  0xffab,     0x6b, 0x004e,   0x45,  78;
+    NumpadMinus => 0xffad,     0x6d, 0x004a,   0x4e,  74;
+    NumpadMultiply => 0xffaa,  0x6a, 0x0037,   0x43,  55;
+    NumpadDivide => 0xffaf,    0x6f, 0xe035,   0x4b,  98;
+
+    CapsLock =>    0xffe5,     0x14, 0x003a,   0x39,  58;
+    NumLock =>     0xff7f,     0x90, 0x0045,   0x47,  69;
+    ScrollLock =>  0xff14,     0x91, 0x0046,   0x6b,  70;
+    PrintScreen => 0xff61,     0x2c, 0xe037,   0x69,  99;
+    Pause =>       0xff13,     0x13, 0xe11d,   0x71,  119;
+    Insert =>      0xff63,     0x2d, 0xe052,   0x72,  110;
+};
+
+/// Codes reported for keys the table folds together, used only when mapping
+/// native codes back to `KeyCode`: right-hand modifiers, the generic Windows
+/// modifier VKs and uppercase letter keysyms
+static X11_ALIASES: &[(u32, KeyCode)] = &[
+    (0xffe2, KeyCode::Shift),
+    (0xffe4, KeyCode::Ctrl),
+    (0xffea, KeyCode::Alt),
+    (0xfe03, KeyCode::Alt), // ISO_Level3_Shift (AltGr)
+    (0xffec, KeyCode::Super),
+    (0xffe7, KeyCode::Super), // Meta_L
+];
+
+static WINDOWS_VK_ALIASES: &[(u16, KeyCode)] = &[
+    (0x10, KeyCode::Shift), // VK_SHIFT
+    (0xa1, KeyCode::Shift), // VK_RSHIFT
+    (0x11, KeyCode::Ctrl),  // VK_CONTROL
+    (0xa3, KeyCode::Ctrl),  // VK_RCONTROL
+    (0x12, KeyCode::Alt),   // VK_MENU
+    (0xa5, KeyCode::Alt),   // VK_RMENU
+    (0x5c, KeyCode::Super), // VK_RWIN
+];
+
+static MACOS_ALIASES: &[(u16, KeyCode)] = &[
+    (0x3c, KeyCode::Shift), // kVK_RightShift
+    (0x3e, KeyCode::Ctrl),  // kVK_RightControl
+    (0x3d, KeyCode::Alt),   // kVK_RightOption
+    (0x36, KeyCode::Super), // kVK_RightCommand
+];
+
+static EVDEV_ALIASES: &[(u16, KeyCode)] = &[
+    (54, KeyCode::Shift),  // KEY_RIGHTSHIFT
+    (97, KeyCode::Ctrl),   // KEY_RIGHTCTRL
+    (100, KeyCode::Alt),   // KEY_RIGHTALT
+    (126, KeyCode::Super), // KEY_RIGHTMETA
+];
+
+/// Table row for a key; `None` only for `KeyCode::Raw`
+pub fn mapping(key: KeyCode) -> Option<&'static KeyMapping> {
+    KEY_TABLE.iter().find(|m| m.key == key)
+}
+
+pub fn x11_keysym(key: KeyCode) -> Option<u32> {
+    mapping(key).map(|m| m.x11_keysym)
+}
+
+pub fn windows_vk(key: KeyCode) -> Option<u16> {
+    mapping(key).map(|m| m.windows_vk)
+}
+
+/// Set-1 scancode without its prefix, and whether it is extended
+/// (`KEYEVENTF_EXTENDEDKEY`)
+pub fn windows_scancode(key: KeyCode) -> Option<(u16, bool)> {
+    mapping(key).map(|m| (m.windows_scancode & 0xff, m.windows_scancode > 0xff))
+}
+
+pub fn macos_keycode(key: KeyCode) -> Option<u16> {
+    mapping(key).map(|m| m.macos_keycode)
+}
+
+pub fn evdev_code(key: KeyCode) -> Option<u16> {
+    mapping(key).map(|m| m.evdev)
+}
+
+/// Key for an X11 keysym, folding case and right-hand modifiers
+pub fn from_x11_keysym(keysym: u32) -> Option<KeyCode> {
+    // Uppercase Latin letters are the shifted keysyms of the letter keys
+    let keysym = match keysym {
+        0x41..=0x5a => keysym + 0x20,
+        other => other,
+    };
+    KEY_TABLE.iter().find(|m| m.x11_keysym == keysym).map(|m| m.key)
+        .or_else(|| alias(X11_ALIASES, keysym))
+}
+
+/// Key for a Windows virtual-key code. `extended` distinguishes keypad Enter.
+pub fn from_windows_vk(vk: u16, extended: bool) -> Option<KeyCode> {
+    if vk == 0x0d && extended {
+        return Some(KeyCode::NumpadEnter);
+    }
+    KEY_TABLE.iter().find(|m| m.windows_vk == vk).map(|m| m.key)
+        .or_else(|| alias(WINDOWS_VK_ALIASES, vk))
+}
+
+pub fn from_macos_keycode(code: u16) -> Option<KeyCode> {
+    KEY_TABLE.iter().find(|m| m.macos_keycode == code).map(|m| m.key)
+        .or_else(|| alias(MACOS_ALIASES, code))
+}
+
+pub fn from_evdev(code: u16) -> Option<KeyCode> {
+    KEY_TABLE.iter().find(|m| m.evdev == code).map(|m| m.key)
+        .or_else(|| alias(EVDEV_ALIASES, code))
+}
+
+fn alias<T: PartialEq>(aliases: &[(T, KeyCode)], code: T) -> Option<KeyCode> {
+    aliases.iter().find(|(c, _)| *c == code).map(|(_, key)| *key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Position of each variant. This match is exhaustive, so a new
+    /// `KeyCode` variant fails to compile until it is listed here, and then
+    /// fails `test_every_variant_has_a_row` until it is in the table.
+    fn ordinal(key: KeyCode) -> Option<usize> {
+        use KeyCode::*;
+        let n = match key {
+            A => 0, B => 1, C => 2, D => 3, E => 4, F => 5, G => 6, H => 7, I => 8,
+            J => 9, K => 10, L => 11, M => 12, N => 13, O => 14, P => 15, Q => 16,
+            R => 17, S => 18, T => 19, U => 20, V => 21, W => 22, X => 23, Y => 24,
+            Z => 25,
+            Key0 => 26, Key1 => 27, Key2 => 28, Key3 => 29, Key4 => 30, Key5 => 31,
+            Key6 => 32, Key7 => 33, Key8 => 34, Key9 => 35,
+            F1 => 36, F2 => 37, F3 => 38, F4 => 39, F5 => 40, F6 => 41, F7 => 42,
+            F8 => 43, F9 => 44, F10 => 45, F11 => 46, F12 => 47,
+            Shift => 48, Ctrl => 49, Alt => 50, Super => 51,
+            Up => 52, Down => 53, Left => 54, Right => 55, Home => 56, End => 57,
+            PageUp => 58, PageDown => 59,
+            Space => 60, Enter => 61, Tab => 62, Backspace => 63, Delete => 64,
+            Escape => 65,
+            Numpad0 => 66, Numpad1 => 67, Numpad2 => 68, Numpad3 => 69, Numpad4 => 70,
+            Numpad5 => 71, Numpad6 => 72, Numpad7 => 73, Numpad8 => 74, Numpad9 => 75,
+            NumpadEnter => 76, NumpadPlus => 77, NumpadMinus => 78,
+            NumpadMultiply => 79, NumpadDivide => 80,
+            CapsLock => 81, NumLock => 82, ScrollLock => 83, PrintScreen => 84,
+            Pause => 85, Insert => 86,
+            Raw(_) => return None,
+        };
+        Some(n)
+    }
+    const VARIANTS: usize = 87;
+
+    #[test]
+    fn test_every_variant_has_a_row() {
+        let mut seen = [false; VARIANTS];
+        for row in KEY_TABLE {
+            let n = ordinal(row.key).expect("Raw has no row");
+            assert!(!seen[n], "{:?} listed twice", row.key);
+            seen[n] = true;
+        }
+        assert!(seen.iter().all(|&s| s), "missing rows: {:?}",
+            (0..VARIANTS).filter(|&n| !seen[n]).collect::<Vec<_>>());
+        assert!(mapping(KeyCode::Raw(5)).is_none());
+    }
+
+    #[test]
+    fn test_no_key_maps_to_unknown() {
+        for row in KEY_TABLE {
+            assert_ne!(row.x11_keysym, 0, "{:?} has no keysym", row.key);
+            assert_ne!(row.windows_vk, 0, "{:?} has no virtual-key code", row.key);
+            assert_ne!(row.windows_scancode, 0, "{:?} has no scancode", row.key);
+            assert_ne!(row.evdev, 0, "{:?} has no evdev code", row.key);
+        }
+        // kVK_ANSI_A is legitimately 0, so the macOS column is checked for
+        // uniqueness below instead
+    }
+
+    #[test]
+    fn test_codes_are_unique_per_platform() {
+        let unique = |codes: Vec<u32>| codes.iter().collect::<HashSet<_>>().len() == codes.len();
+        assert!(unique(KEY_TABLE.iter().map(|m| m.x11_keysym).collect()));
+        assert!(unique(KEY_TABLE.iter().map(|m| m.windows_scancode as u32).collect()));
+        assert!(unique(KEY_TABLE.iter().map(|m| m.macos_keycode as u32).collect()));
+        assert!(unique(KEY_TABLE.iter().map(|m| m.evdev as u32).collect()));
+        // Virtual-key codes are unique apart from the two Enter keys
+        let vks: Vec<u32> = KEY_TABLE.iter()
+            .filter(|m| m.key != KeyCode::NumpadEnter)
+            .map(|m| m.windows_vk as u32)
+            .collect();
+        assert!(unique(vks));
+    }
+
+    #[test]
+    fn test_reverse_mappings_round_trip() {
+        for row in KEY_TABLE {
+            let key = row.key;
+            assert_eq!(from_x11_keysym(row.x11_keysym), Some(key));
+            assert_eq!(from_windows_vk(row.windows_vk, row.windows_scancode > 0xff), Some(key), "{:?}", key);
+            assert_eq!(from_macos_keycode(row.macos_keycode), Some(key));
+            assert_eq!(from_evdev(row.evdev), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_reverse_aliases() {
+        assert_eq!(from_x11_keysym(0x51), Some(KeyCode::Q));
+        assert_eq!(from_x11_keysym(0xffe2), Some(KeyCode::Shift));
+        assert_eq!(from_windows_vk(0x11, false), Some(KeyCode::Ctrl));
+        assert_eq!(from_windows_vk(0x0d, false), Some(KeyCode::Enter));
+        assert_eq!(from_macos_keycode(0x36), Some(KeyCode::Super));
+        assert_eq!(from_evdev(100), Some(KeyCode::Alt));
+        assert_eq!(from_evdev(0x2ff), None);
+    }
+
+    #[test]
+    fn test_scancode_prefix() {
+        assert_eq!(windows_scancode(KeyCode::A), Some((0x1e, false)));
+        assert_eq!(windows_scancode(KeyCode::Up), Some((0x48, true)));
+        assert_eq!(windows_scancode(KeyCode::NumpadEnter), Some((0x1c, true)));
+    }
+}
@@ -0,0 +1,164 @@
+//! Key translation for Linux input backends
+//!
+//! The RemoteDesktop portal takes X keysyms, which leave the keyboard layout
+//! to the compositor; uinput takes evdev key codes, which are layout-blind
+//! and assume a US layout for text. Per-key codes come from
+//! [`crate::input::keycodes`]; this adds the character tables for typing.
+
+use crate::input::{keycodes, KeyCode};
+
+/// Keysyms used when typing text
+pub mod keysyms {
+    pub const XK_BACKSPACE: u32 = 0xff08;
+    pub const XK_TAB: u32 = 0xff09;
+    pub const XK_RETURN: u32 = 0xff0d;
+
+    /// Keysyms for Unicode characters outside Latin-1 are the code point
+    /// offset by this
+    pub const UNICODE_OFFSET: u32 = 0x0100_0000;
+}
+
+/// Keysym for a key, or `None` for `KeyCode::Raw`, which carries an evdev
+/// code rather than a symbol
+pub fn keysym(key: KeyCode) -> Option<u32> {
+    keycodes::x11_keysym(key)
+}
+
+/// Keysym that types `c`
+pub fn char_keysym(c: char) -> u32 {
+    use keysyms::*;
+
+    match c {
+        '\n' | '\r' => XK_RETURN,
+        '\t' => XK_TAB,
+        '\u{8}' => XK_BACKSPACE,
+        // Printable ASCII and Latin-1 keysyms equal their code points
+        ' '..='~' | '\u{a0}'..='\u{ff}' => c as u32,
+        _ => UNICODE_OFFSET + c as u32,
+    }
+}
+
+/// evdev key code for a key; `KeyCode::Raw` already is one
+pub fn evdev_code(key: KeyCode) -> u16 {
+    match key {
+        KeyCode::Raw(code) => code as u16,
+        key => keycodes::evdev_code(key).unwrap_or_default(),
+    }
+}
+
+/// evdev key code and whether Shift is needed to type `c` on a US layout
+pub fn char_evdev(c: char) -> Option<(u16, bool)> {
+    const LETTERS: &[KeyCode] = &[
+        KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G,
+        KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N,
+        KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R, KeyCode::S, KeyCode::T, KeyCode::U,
+        KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+    ];
+
+    let mapping = match c {
+        'a'..='z' => (evdev_code(LETTERS[(c as u8 - b'a') as usize]), false),
+        'A'..='Z' => (evdev_code(LETTERS[(c as u8 - b'A') as usize]), true),
+        '1'..='9' => (2 + (c as u8 - b'1') as u16, false),
+        '0' => (11, false),
+        ' ' => (57, false),
+        '\n' | '\r' => (28, false),
+        '\t' => (15, false),
+        '-' => (12, false),
+        '=' => (13, false),
+        '[' => (26, false),
+        ']' => (27, false),
+        ';' => (39, false),
+        '\'' => (40, false),
+        '`' => (41, false),
+        '\\' => (43, false),
+        ',' => (51, false),
+        '.' => (52, false),
+        '/' => (53, false),
+        '!' => (2, true),
+        '@' => (3, true),
+        '#' => (4, true),
+        '$' => (5, true),
+        '%' => (6, true),
+        '^' => (7, true),
+        '&' => (8, true),
+        '*' => (9, true),
+        '(' => (10, true),
+        ')' => (11, true),
+        '_' => (12, true),
+        '+' => (13, true),
+        '{' => (26, true),
+        '}' => (27, true),
+        ':' => (39, true),
+        '"' => (40, true),
+        '~' => (41, true),
+        '|' => (43, true),
+        '<' => (51, true),
+        '>' => (52, true),
+        '?' => (53, true),
+        _ => return None,
+    };
+    Some(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keysym_table() {
+        let cases = [
+            (KeyCode::A, 0x61),
+            (KeyCode::Z, 0x7a),
+            (KeyCode::Key0, 0x30),
+            (KeyCode::Key9, 0x39),
+            (KeyCode::F1, 0xffbe),
+            (KeyCode::F12, 0xffc9),
+            (KeyCode::Shift, 0xffe1),
+            (KeyCode::Ctrl, 0xffe3),
+            (KeyCode::Alt, 0xffe9),
+            (KeyCode::Super, 0xffeb),
+            (KeyCode::Left, 0xff51),
+            (KeyCode::Down, 0xff54),
+            (KeyCode::PageDown, 0xff56),
+            (KeyCode::Space, 0x20),
+            (KeyCode::Enter, 0xff0d),
+            (KeyCode::Delete, 0xffff),
+            (KeyCode::Numpad0, 0xffb0),
+            (KeyCode::Numpad9, 0xffb9),
+            (KeyCode::NumpadEnter, 0xff8d),
+            (KeyCode::NumpadDivide, 0xffaf),
+            (KeyCode::PrintScreen, 0xff61),
+            (KeyCode::Insert, 0xff63),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(keysym(key), Some(expected), "{:?}", key);
+        }
+        assert_eq!(keysym(KeyCode::Raw(30)), None);
+    }
+
+    #[test]
+    fn test_char_keysyms() {
+        assert_eq!(char_keysym('a'), 0x61);
+        assert_eq!(char_keysym('A'), 0x41);
+        assert_eq!(char_keysym('~'), 0x7e);
+        assert_eq!(char_keysym('é'), 0xe9);
+        assert_eq!(char_keysym('\n'), keysyms::XK_RETURN);
+        assert_eq!(char_keysym('€'), 0x0100_20ac);
+    }
+
+    #[test]
+    fn test_evdev_table() {
+        assert_eq!(evdev_code(KeyCode::A), 30);
+        assert_eq!(evdev_code(KeyCode::Key0), 11);
+        assert_eq!(evdev_code(KeyCode::Delete), 111);
+        assert_eq!(evdev_code(KeyCode::F11), 87);
+        assert_eq!(evdev_code(KeyCode::Raw(183)), 183);
+
+        assert_eq!(char_evdev('q'), Some((16, false)));
+        assert_eq!(char_evdev('Q'), Some((16, true)));
+        assert_eq!(char_evdev('5'), Some((6, false)));
+        assert_eq!(char_evdev('%'), Some((6, true)));
+        assert_eq!(char_evdev('?'), Some((53, true)));
+        assert_eq!(char_evdev('é'), None);
+    }
+}
@@ -1,5 +1,7 @@
+pub mod keymap;
+pub mod uinput;
 pub mod wayland;
 pub mod x11;
 
 pub use wayland::WaylandInputHandler;
-pub use x11::X11InputHandler;
\ No newline at end of file
+pub use x11::X11InputHandler;
@@ -0,0 +1,225 @@
+//! Input injection through /dev/uinput
+//!
+//! Fallback for Wayland compositors without the RemoteDesktop portal. Events
+//! go in below the compositor, so this works anywhere the agent can open
+//! /dev/uinput, but keys are evdev codes (text assumes a US layout) and the
+//! pointer is positioned across the whole desktop rather than one output.
+
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+
+use crate::error::{GhostLinkError, InputError, Result};
+
+pub const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Range of the virtual pointer's absolute axes
+pub const ABS_MAX: i32 = 65535;
+
+/// Check that /dev/uinput can be opened for writing, explaining how to fix
+/// it if not
+pub fn check_access() -> Result<()> {
+    match OpenOptions::new().write(true).open(UINPUT_PATH) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(GhostLinkError::Input(InputError::MethodUnavailable {
+            method: access_error(e.kind()),
+        })),
+    }
+}
+
+fn access_error(kind: ErrorKind) -> String {
+    match kind {
+        ErrorKind::NotFound => format!(
+            "uinput ({} does not exist; load the module with `modprobe uinput`)",
+            UINPUT_PATH
+        ),
+        ErrorKind::PermissionDenied => format!(
+            "uinput ({} is not writable by this user; add a udev rule such as \
+             KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\" and add the user to the input group)",
+            UINPUT_PATH
+        ),
+        other => format!("uinput ({}: {})", UINPUT_PATH, other),
+    }
+}
+
+#[cfg(feature = "native-input")]
+pub use device::UinputDevice;
+
+#[cfg(feature = "native-input")]
+mod device {
+    use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+    use evdev::{
+        AbsInfo, AbsoluteAxisType, AttributeSet, EventType, InputEvent, Key, RelativeAxisType,
+        UinputAbsSetup,
+    };
+    use parking_lot::Mutex;
+
+    use super::ABS_MAX;
+    use crate::error::{GhostLinkError, InputError, Result};
+
+    const BTN_LEFT: u16 = 0x110;
+    const BTN_EXTRA: u16 = 0x114;
+
+    /// A virtual keyboard, an absolute pointer and a relative mouse
+    pub struct UinputDevice {
+        keyboard: Mutex<VirtualDevice>,
+        pointer: Mutex<VirtualDevice>,
+        mouse: Mutex<VirtualDevice>,
+    }
+
+    impl UinputDevice {
+        pub fn new() -> Result<Self> {
+            super::check_access()?;
+
+            // Every key below the button range; button codes go on the pointer
+            let mut keys = AttributeSet::<Key>::new();
+            for code in 1..BTN_LEFT {
+                keys.insert(Key::new(code));
+            }
+            let keyboard = VirtualDeviceBuilder::new()
+                .and_then(|b| b.name("GhostLink virtual keyboard").with_keys(&keys))
+                .and_then(|b| b.build())
+                .map_err(create_failed)?;
+
+            // An absolute pointer with buttons is treated as a tablet-style
+            // mouse (like a VM's USB tablet) and spans the whole desktop
+            let mut buttons = AttributeSet::<Key>::new();
+            for code in BTN_LEFT..=BTN_EXTRA {
+                buttons.insert(Key::new(code));
+            }
+            let mut wheels = AttributeSet::<RelativeAxisType>::new();
+            wheels.insert(RelativeAxisType::REL_WHEEL);
+            wheels.insert(RelativeAxisType::REL_HWHEEL);
+            let axis = AbsInfo::new(0, 0, ABS_MAX, 0, 0, 0);
+            let pointer = VirtualDeviceBuilder::new()
+                .and_then(|b| b.name("GhostLink virtual pointer").with_keys(&buttons))
+                .and_then(|b| b.with_relative_axes(&wheels))
+                .and_then(|b| b.with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_X, axis)))
+                .and_then(|b| b.with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, axis)))
+                .and_then(|b| b.build())
+                .map_err(create_failed)?;
+
+            // Relative motion needs its own device: one reporting both
+            // absolute and relative axes isn't treated as a mouse. It needs a
+            // button to be seen as a pointer at all; clicks still go through
+            // the absolute pointer.
+            let mut axes = AttributeSet::<RelativeAxisType>::new();
+            axes.insert(RelativeAxisType::REL_X);
+            axes.insert(RelativeAxisType::REL_Y);
+            let mut left = AttributeSet::<Key>::new();
+            left.insert(Key::new(BTN_LEFT));
+            let mouse = VirtualDeviceBuilder::new()
+                .and_then(|b| b.name("GhostLink virtual mouse").with_keys(&left))
+                .and_then(|b| b.with_relative_axes(&axes))
+                .and_then(|b| b.build())
+                .map_err(create_failed)?;
+
+            Ok(Self {
+                keyboard: Mutex::new(keyboard),
+                pointer: Mutex::new(pointer),
+                mouse: Mutex::new(mouse),
+            })
+        }
+
+        /// Press or release a key by evdev code
+        pub fn key(&self, code: u16, pressed: bool) -> Result<()> {
+            self.keyboard.lock()
+                .emit(&[InputEvent::new(EventType::KEY, code, pressed as i32)])
+                .map_err(|_| GhostLinkError::Input(InputError::KeyMappingFailed {
+                    key: format!("evdev {}", code),
+                }))
+        }
+
+        /// Move the pointer to a position in 0..=ABS_MAX on both axes
+        pub fn move_absolute(&self, x: i32, y: i32) -> Result<()> {
+            self.pointer.lock()
+                .emit(&[
+                    InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, x),
+                    InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, y),
+                ])
+                .map_err(|_| GhostLinkError::Input(InputError::InvalidCoordinates { x, y }))
+        }
+
+        /// Move the pointer by a delta, as a physical mouse would
+        pub fn move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+            self.mouse.lock()
+                .emit(&[
+                    InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, dx),
+                    InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, dy),
+                ])
+                .map_err(|_| GhostLinkError::Input(InputError::InvalidCoordinates { x: dx, y: dy }))
+        }
+
+        /// Press or release a pointer button by evdev code
+        pub fn button(&self, code: u16, pressed: bool) -> Result<()> {
+            self.pointer.lock()
+                .emit(&[InputEvent::new(EventType::KEY, code, pressed as i32)])
+                .map_err(|e| GhostLinkError::Other(format!("uinput button failed: {}", e)))
+        }
+
+        /// Scroll by wheel notches; positive is up or right
+        pub fn scroll(&self, horizontal: i32, vertical: i32) -> Result<()> {
+            let mut events = Vec::new();
+            if vertical != 0 {
+                events.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_WHEEL.0, vertical));
+            }
+            if horizontal != 0 {
+                events.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_HWHEEL.0, horizontal));
+            }
+            self.pointer.lock()
+                .emit(&events)
+                .map_err(|e| GhostLinkError::Other(format!("uinput scroll failed: {}", e)))
+        }
+    }
+
+    fn create_failed(e: std::io::Error) -> GhostLinkError {
+        GhostLinkError::Input(InputError::MethodUnavailable {
+            method: format!("uinput (creating virtual device failed: {})", e),
+        })
+    }
+}
+
+/// Stand-in when built without evdev support, so the fallback reports why it
+/// can't be used
+#[cfg(not(feature = "native-input"))]
+pub struct UinputDevice;
+
+#[cfg(not(feature = "native-input"))]
+impl UinputDevice {
+    pub fn new() -> Result<Self> {
+        check_access()?;
+        Err(GhostLinkError::Input(InputError::MethodUnavailable {
+            method: "uinput (this build lacks the native-input feature)".to_string(),
+        }))
+    }
+
+    pub fn key(&self, _code: u16, _pressed: bool) -> Result<()> {
+        unreachable!("UinputDevice cannot be constructed without native-input")
+    }
+
+    pub fn move_absolute(&self, _x: i32, _y: i32) -> Result<()> {
+        unreachable!("UinputDevice cannot be constructed without native-input")
+    }
+
+    pub fn move_relative(&self, _dx: i32, _dy: i32) -> Result<()> {
+        unreachable!("UinputDevice cannot be constructed without native-input")
+    }
+
+    pub fn button(&self, _code: u16, _pressed: bool) -> Result<()> {
+        unreachable!("UinputDevice cannot be constructed without native-input")
+    }
+
+    pub fn scroll(&self, _horizontal: i32, _vertical: i32) -> Result<()> {
+        unreachable!("UinputDevice cannot be constructed without native-input")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_errors_explain_the_fix() {
+        assert!(access_error(ErrorKind::NotFound).contains("modprobe uinput"));
+        assert!(access_error(ErrorKind::PermissionDenied).contains("udev rule"));
+    }
+}
@@ -1,11 +1,35 @@
-use anyhow::Result;
-use std::process::Command;
+use crate::error::Result;
+use parking_lot::Mutex;
 use tracing::{debug, error, info, warn};
 
+use crate::capture::wayland::portal::{self, SharedPortalSession, StreamInfo};
+use crate::error::{GhostLinkError, InputError};
+use crate::input::wayland_portal::{button_codes, Axis, ButtonState, KeyState, RemoteDesktopPortal};
 use crate::input::{InputHandler, KeyCode, MouseButton};
 
-/// Wayland input handler using external tools
+use super::keymap;
+use super::uinput::{self, UinputDevice};
+
+/// Where input is being sent
+enum Backend {
+    /// The RemoteDesktop portal session shared with the screen capturer
+    Portal(RemoteDesktopPortal),
+    /// Virtual devices on /dev/uinput
+    Uinput(UinputDevice),
+    /// Neither is usable; holds the reason
+    Unavailable(String),
+}
+
+/// Wayland input handler
+///
+/// Injects through the RemoteDesktop portal when the capturer obtained a
+/// remote desktop session, and through uinput otherwise. The backend is
+/// re-resolved when the capture session changes, since capture may be
+/// (re)started after this handler is initialized.
 pub struct WaylandInputHandler {
+    backend: Mutex<Backend>,
+    /// Portal session the backend was resolved for
+    resolved_for: Mutex<Option<dbus::Path<'static>>>,
     is_healthy: bool,
     input_blocked: bool,
 }
@@ -13,134 +37,143 @@ pub struct WaylandInputHandler {
 impl WaylandInputHandler {
     pub async fn new() -> Result<Self> {
         Ok(Self {
-            is_healthy: true,
+            backend: Mutex::new(Backend::Unavailable("not initialized".to_string())),
+            resolved_for: Mutex::new(None),
+            is_healthy: false,
             input_blocked: false,
         })
     }
 
-    /// Check if required tools are available
-    fn check_tools() -> Result<()> {
-        // Check for ydotool (for input simulation)
-        let ydotool_check = Command::new("which")
-            .arg("ydotool")
-            .output();
-        
-        if ydotool_check.is_err() || !ydotool_check.unwrap().status.success() {
-            return Err(anyhow::anyhow!("ydotool not found. Please install ydotool for Wayland input support"));
+    /// Pick a backend for the given capture session
+    fn resolve(shared: Option<&SharedPortalSession>) -> Backend {
+        let portal_error = match shared {
+            Some(session) => match RemoteDesktopPortal::connect(session) {
+                Ok(portal) => {
+                    info!("Injecting input through the RemoteDesktop portal");
+                    return Backend::Portal(portal);
+                }
+                Err(e) => e.to_string(),
+            },
+            None => "no RemoteDesktop session from screen capture".to_string(),
+        };
+
+        match UinputDevice::new() {
+            Ok(device) => {
+                info!("RemoteDesktop portal unavailable ({}); injecting input through uinput", portal_error);
+                Backend::Uinput(device)
+            }
+            Err(e) => Backend::Unavailable(format!("{}; {}", portal_error, e)),
+        }
+    }
+
+    /// Run `f` against the backend for the current capture session
+    fn with_backend<T>(
+        &self,
+        f: impl FnOnce(&Backend, Option<&SharedPortalSession>) -> Result<T>,
+    ) -> Result<T> {
+        let shared = portal::shared_session();
+        let session_path = shared.as_ref().map(|s| s.session_path.clone());
+
+        // Retry an unusable backend too: the user may since have approved
+        // the portal or fixed /dev/uinput permissions
+        let mut backend = self.backend.lock();
+        let mut resolved_for = self.resolved_for.lock();
+        if *resolved_for != session_path || matches!(*backend, Backend::Unavailable(_)) {
+            *backend = Self::resolve(shared.as_ref());
+            *resolved_for = session_path;
         }
 
-        Ok(())
+        if let Backend::Unavailable(reason) = &*backend {
+            return Err(GhostLinkError::Input(InputError::MethodUnavailable {
+                method: format!("Wayland input injection ({})", reason),
+            }));
+        }
+        f(&backend, shared.as_ref())
     }
 
-    /// Convert mouse button to ydotool format
-    fn mouse_button_to_ydotool(&self, button: MouseButton) -> &str {
+    /// Portal button code for a mouse button
+    fn button_code(button: MouseButton) -> i32 {
         match button {
-            MouseButton::Left => "0x110",
-            MouseButton::Right => "0x111", 
-            MouseButton::Middle => "0x112",
-            MouseButton::X1 => "0x113",
-            MouseButton::X2 => "0x114",
+            MouseButton::Left => button_codes::BTN_LEFT,
+            MouseButton::Right => button_codes::BTN_RIGHT,
+            MouseButton::Middle => button_codes::BTN_MIDDLE,
+            MouseButton::X1 => button_codes::BTN_SIDE,
+            MouseButton::X2 => button_codes::BTN_EXTRA,
         }
     }
 
-    /// Convert key code to ydotool format
-    fn keycode_to_ydotool(&self, key: KeyCode) -> Option<String> {
-        match key {
-            // Letters (lowercase)
-            KeyCode::A => Some("0x1e".to_string()),
-            KeyCode::B => Some("0x30".to_string()),
-            KeyCode::C => Some("0x2e".to_string()),
-            KeyCode::D => Some("0x20".to_string()),
-            KeyCode::E => Some("0x12".to_string()),
-            KeyCode::F => Some("0x21".to_string()),
-            KeyCode::G => Some("0x22".to_string()),
-            KeyCode::H => Some("0x23".to_string()),
-            KeyCode::I => Some("0x17".to_string()),
-            KeyCode::J => Some("0x24".to_string()),
-            KeyCode::K => Some("0x25".to_string()),
-            KeyCode::L => Some("0x26".to_string()),
-            KeyCode::M => Some("0x32".to_string()),
-            KeyCode::N => Some("0x31".to_string()),
-            KeyCode::O => Some("0x18".to_string()),
-            KeyCode::P => Some("0x19".to_string()),
-            KeyCode::Q => Some("0x10".to_string()),
-            KeyCode::R => Some("0x13".to_string()),
-            KeyCode::S => Some("0x1f".to_string()),
-            KeyCode::T => Some("0x14".to_string()),
-            KeyCode::U => Some("0x16".to_string()),
-            KeyCode::V => Some("0x2f".to_string()),
-            KeyCode::W => Some("0x11".to_string()),
-            KeyCode::X => Some("0x2d".to_string()),
-            KeyCode::Y => Some("0x15".to_string()),
-            KeyCode::Z => Some("0x2c".to_string()),
-            
-            // Numbers
-            KeyCode::Key0 => Some("0x0b".to_string()),
-            KeyCode::Key1 => Some("0x02".to_string()),
-            KeyCode::Key2 => Some("0x03".to_string()),
-            KeyCode::Key3 => Some("0x04".to_string()),
-            KeyCode::Key4 => Some("0x05".to_string()),
-            KeyCode::Key5 => Some("0x06".to_string()),
-            KeyCode::Key6 => Some("0x07".to_string()),
-            KeyCode::Key7 => Some("0x08".to_string()),
-            KeyCode::Key8 => Some("0x09".to_string()),
-            KeyCode::Key9 => Some("0x0a".to_string()),
-            
-            // Special keys
-            KeyCode::Space => Some("0x39".to_string()),
-            KeyCode::Enter => Some("0x1c".to_string()),
-            KeyCode::Tab => Some("0x0f".to_string()),
-            KeyCode::Backspace => Some("0x0e".to_string()),
-            KeyCode::Delete => Some("0x53".to_string()),
-            KeyCode::Escape => Some("0x01".to_string()),
-            
-            // Arrow keys
-            KeyCode::Up => Some("0x67".to_string()),
-            KeyCode::Down => Some("0x6c".to_string()),
-            KeyCode::Left => Some("0x69".to_string()),
-            KeyCode::Right => Some("0x6a".to_string()),
-            
-            // Modifiers
-            KeyCode::Shift => Some("0x2a".to_string()),
-            KeyCode::Ctrl => Some("0x1d".to_string()),
-            KeyCode::Alt => Some("0x38".to_string()),
-            KeyCode::Super => Some("0x7d".to_string()),
-            
-            // Function keys
-            KeyCode::F1 => Some("0x3b".to_string()),
-            KeyCode::F2 => Some("0x3c".to_string()),
-            KeyCode::F3 => Some("0x3d".to_string()),
-            KeyCode::F4 => Some("0x3e".to_string()),
-            KeyCode::F5 => Some("0x3f".to_string()),
-            KeyCode::F6 => Some("0x40".to_string()),
-            KeyCode::F7 => Some("0x41".to_string()),
-            KeyCode::F8 => Some("0x42".to_string()),
-            KeyCode::F9 => Some("0x43".to_string()),
-            KeyCode::F10 => Some("0x44".to_string()),
-            KeyCode::F11 => Some("0x57".to_string()),
-            KeyCode::F12 => Some("0x58".to_string()),
-            
-            KeyCode::Raw(code) => Some(format!("0x{:x}", code)),
-            _ => {
-                warn!("Unmapped key code: {:?}", key);
-                None
-            }
+    /// Type one character through the portal as a keysym press and release
+    fn type_char_portal(portal: &RemoteDesktopPortal, c: char) -> Result<()> {
+        let keysym = keymap::char_keysym(c);
+        portal.notify_keyboard_keysym(keysym, KeyState::Pressed)?;
+        portal.notify_keyboard_keysym(keysym, KeyState::Released)?;
+        Ok(())
+    }
+
+    /// Type one character through uinput, holding Shift where needed
+    fn type_char_uinput(device: &UinputDevice, c: char) -> Result<()> {
+        const KEY_LEFTSHIFT: u16 = 42;
+
+        let Some((code, shift)) = keymap::char_evdev(c) else {
+            warn!("Cannot type {:?} through uinput; skipping", c);
+            return Ok(());
+        };
+        if shift {
+            device.key(KEY_LEFTSHIFT, true)?;
+        }
+        device.key(code, true)?;
+        device.key(code, false)?;
+        if shift {
+            device.key(KEY_LEFTSHIFT, false)?;
         }
+        Ok(())
+    }
+}
+
+/// Portal stream and stream-local position for a point on the captured
+/// display. The viewer sees only the selected stream, so coordinates are
+/// relative to it; they are clamped to its bounds when its size is known.
+pub fn stream_position(streams: &[StreamInfo], selected: usize, x: i32, y: i32) -> Option<(u32, f64, f64)> {
+    let stream = streams.get(selected).or_else(|| streams.first())?;
+    let (x, y) = match stream.size {
+        (0, 0) => (x.max(0), y.max(0)),
+        (width, height) => (x.clamp(0, width as i32 - 1), y.clamp(0, height as i32 - 1)),
+    };
+    Some((stream.path as u32, x as f64, y as f64))
+}
+
+/// Position on a uinput absolute axis (0..=`max`) for a point on the captured
+/// display. An absolute uinput pointer spans the bounding box of all outputs,
+/// so the stream's offset in the desktop layout is added first.
+pub fn layout_position(streams: &[StreamInfo], selected: usize, x: i32, y: i32, max: i32) -> Option<(i32, i32)> {
+    let stream = streams.get(selected).or_else(|| streams.first())?;
+    let left = streams.iter().map(|s| s.position.0).min()?;
+    let top = streams.iter().map(|s| s.position.1).min()?;
+    let right = streams.iter().map(|s| s.position.0 + s.size.0 as i32).max()?;
+    let bottom = streams.iter().map(|s| s.position.1 + s.size.1 as i32).max()?;
+    let (width, height) = (right - left, bottom - top);
+    if width <= 1 || height <= 1 {
+        return None;
     }
+
+    let x = (stream.position.0 + x - left).clamp(0, width - 1);
+    let y = (stream.position.1 + y - top).clamp(0, height - 1);
+    let scale = |v: i32, extent: i32| (v as i64 * max as i64 / (extent - 1) as i64) as i32;
+    Some((scale(x, width), scale(y, height)))
 }
 
 #[async_trait::async_trait]
 impl InputHandler for WaylandInputHandler {
     async fn initialize(&mut self) -> Result<()> {
         info!("Initializing Wayland input handler");
-        
-        // Check if required tools are available
-        if let Err(e) = Self::check_tools() {
+
+        let result = self.with_backend(|_, _| Ok(()));
+        if let Err(e) = &result {
             error!("Failed to initialize Wayland input handler: {}", e);
             self.is_healthy = false;
-            return Err(e);
+            return result;
         }
-        
+
         self.is_healthy = true;
         info!("Wayland input handler initialized successfully");
         Ok(())
@@ -148,110 +181,134 @@ impl InputHandler for WaylandInputHandler {
 
     async fn handle_mouse_move(&self, x: i32, y: i32) -> Result<()> {
         debug!("Moving mouse to ({}, {})", x, y);
-        
-        let output = Command::new("ydotool")
-            .args(&["mousemove", &format!("{}", x), &format!("{}", y)])
-            .output()
-            .map_err(|e| anyhow::anyhow!("Failed to execute ydotool mousemove: {}", e))?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow::anyhow!("ydotool mousemove failed: {}", stderr));
-        }
 
-        Ok(())
+        self.with_backend(|backend, shared| {
+            let streams = shared.map(|s| s.streams.as_slice()).unwrap_or_default();
+            let selected = shared.map(|s| s.selected_stream).unwrap_or(0);
+            match backend {
+                Backend::Portal(portal) => {
+                    let (stream, x, y) = stream_position(streams, selected, x, y)
+                        .ok_or(GhostLinkError::Input(InputError::InvalidCoordinates { x, y }))?;
+                    portal.notify_pointer_motion_absolute(x, y, stream)?;
+                }
+                Backend::Uinput(device) => {
+                    // Without stream geometry there's no way to place an
+                    // absolute pointer
+                    let (x, y) = layout_position(streams, selected, x, y, uinput::ABS_MAX)
+                        .ok_or(GhostLinkError::Input(InputError::InvalidCoordinates { x, y }))?;
+                    device.move_absolute(x, y)?;
+                }
+                Backend::Unavailable(_) => unreachable!(),
+            }
+            Ok(())
+        })
+    }
+
+    async fn handle_mouse_move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+        debug!("Moving mouse by ({}, {})", dx, dy);
+
+        self.with_backend(|backend, _| {
+            match backend {
+                Backend::Portal(portal) => portal.notify_pointer_motion(dx as f64, dy as f64)?,
+                Backend::Uinput(device) => device.move_relative(dx, dy)?,
+                Backend::Unavailable(_) => unreachable!(),
+            }
+            Ok(())
+        })
+    }
+
+    fn supports_relative_motion(&self) -> bool {
+        // Both backends can; which one is in use is only known per session
+        true
     }
 
     async fn handle_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()> {
         debug!("Mouse button {:?} {}", button, if pressed { "pressed" } else { "released" });
-        
-        let button_code = self.mouse_button_to_ydotool(button);
-        let action = if pressed { "1" } else { "0" };
-        
-        let output = Command::new("ydotool")
-            .args(&["click", button_code, action])
-            .output()
-            .map_err(|e| anyhow::anyhow!("Failed to execute ydotool click: {}", e))?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow::anyhow!("ydotool click failed: {}", stderr));
-        }
 
-        Ok(())
+        let code = Self::button_code(button);
+        self.with_backend(|backend, _| {
+            match backend {
+                Backend::Portal(portal) => {
+                    let state = if pressed { ButtonState::Pressed } else { ButtonState::Released };
+                    portal.notify_pointer_button(code, state)?;
+                }
+                Backend::Uinput(device) => device.button(code as u16, pressed)?,
+                Backend::Unavailable(_) => unreachable!(),
+            }
+            Ok(())
+        })
     }
 
     async fn handle_mouse_scroll(&self, delta_x: i32, delta_y: i32) -> Result<()> {
         debug!("Mouse scroll delta: ({}, {})", delta_x, delta_y);
-        
-        if delta_y != 0 {
-            let scroll_direction = if delta_y > 0 { "4" } else { "5" };
-            
-            let output = Command::new("ydotool")
-                .args(&["click", scroll_direction])
-                .output()
-                .map_err(|e| anyhow::anyhow!("Failed to execute ydotool scroll: {}", e))?;
-
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                return Err(anyhow::anyhow!("ydotool scroll failed: {}", stderr));
-            }
-        }
 
-        // Note: Horizontal scrolling is more complex with ydotool
-        if delta_x != 0 {
-            warn!("Horizontal scrolling not fully supported with ydotool");
-        }
-
-        Ok(())
+        // One notch per event, positive delta_y scrolling up as on the other
+        // platforms
+        let (notch_x, notch_y) = (delta_x.signum(), delta_y.signum());
+        self.with_backend(|backend, _| {
+            match backend {
+                Backend::Portal(portal) => {
+                    // Portal steps are positive downwards
+                    if notch_y != 0 {
+                        portal.notify_pointer_axis(Axis::Vertical, -notch_y)?;
+                    }
+                    if notch_x != 0 {
+                        portal.notify_pointer_axis(Axis::Horizontal, notch_x)?;
+                    }
+                }
+                Backend::Uinput(device) => device.scroll(notch_x, notch_y)?,
+                Backend::Unavailable(_) => unreachable!(),
+            }
+            Ok(())
+        })
     }
 
     async fn handle_key_event(&self, key: KeyCode, pressed: bool) -> Result<()> {
         debug!("Key {:?} {}", key, if pressed { "pressed" } else { "released" });
-        
-        if let Some(key_code) = self.keycode_to_ydotool(key) {
-            let action = if pressed { "1" } else { "0" };
-            
-            let output = Command::new("ydotool")
-                .args(&["key", &format!("{}:{}", key_code, action)])
-                .output()
-                .map_err(|e| anyhow::anyhow!("Failed to execute ydotool key: {}", e))?;
-
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                return Err(anyhow::anyhow!("ydotool key failed: {}", stderr));
-            }
-        } else {
-            warn!("Cannot map key {:?} to ydotool format", key);
-        }
 
-        Ok(())
+        self.with_backend(|backend, _| {
+            match backend {
+                Backend::Portal(portal) => {
+                    let state = if pressed { KeyState::Pressed } else { KeyState::Released };
+                    match keymap::keysym(key) {
+                        Some(keysym) => portal.notify_keyboard_keysym(keysym, state)?,
+                        // Raw codes are evdev codes, which the portal takes as keycodes
+                        None => portal.notify_keyboard_keycode(keymap::evdev_code(key) as u32, state)?,
+                    }
+                }
+                Backend::Uinput(device) => device.key(keymap::evdev_code(key), pressed)?,
+                Backend::Unavailable(_) => unreachable!(),
+            }
+            Ok(())
+        })
     }
 
     async fn handle_text_input(&self, text: &str) -> Result<()> {
-        debug!("Typing text: {}", text);
-        
-        let output = Command::new("ydotool")
-            .args(&["type", text])
-            .output()
-            .map_err(|e| anyhow::anyhow!("Failed to execute ydotool type: {}", e))?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow::anyhow!("ydotool type failed: {}", stderr));
-        }
+        debug!("Typing {} characters", text.chars().count());
 
-        Ok(())
+        self.with_backend(|backend, _| {
+            for c in text.chars() {
+                match backend {
+                    Backend::Portal(portal) => Self::type_char_portal(portal, c)?,
+                    Backend::Uinput(device) => Self::type_char_uinput(device, c)?,
+                    Backend::Unavailable(_) => unreachable!(),
+                }
+            }
+            Ok(())
+        })
     }
 
     async fn block_user_input(&self) -> Result<()> {
-        warn!("Input blocking not implemented for Wayland - this is complex and requires root access");
-        // TODO: Implement input blocking using udev rules or similar
-        Ok(())
+        // Neither the portal nor uinput can stop the local user's devices;
+        // that would need the compositor's cooperation
+        Err(GhostLinkError::Input(InputError::Unsupported {
+            operation: "Blocking local input".to_string(),
+            platform: "Wayland".to_string(),
+        }))
     }
 
     async fn unblock_user_input(&self) -> Result<()> {
-        warn!("Input unblocking not implemented for Wayland");
+        // Input is never blocked on Wayland, so there is nothing to undo
         Ok(())
     }
 
@@ -265,7 +322,78 @@ impl InputHandler for WaylandInputHandler {
 
     async fn cleanup(&mut self) -> Result<()> {
         info!("Cleaning up Wayland input handler");
-        // No cleanup needed for external tool approach
+        // Dropping the portal client leaves the session to the capturer;
+        // dropping the uinput device removes the virtual devices
+        *self.backend.lock() = Backend::Unavailable("cleaned up".to_string());
+        *self.resolved_for.lock() = None;
+        self.is_healthy = false;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::wayland::portal::SourceType;
+
+    fn stream(path: u64, position: (i32, i32), size: (u32, u32)) -> StreamInfo {
+        StreamInfo { path, source_type: SourceType::Monitor, position, size }
+    }
+
+    #[test]
+    fn test_stream_position_uses_selected_stream() {
+        let streams = [stream(40, (0, 0), (1920, 1080)), stream(41, (1920, 0), (1280, 1024))];
+        assert_eq!(stream_position(&streams, 1, 100, 200), Some((41, 100.0, 200.0)));
+        // Clamped to the stream
+        assert_eq!(stream_position(&streams, 1, 5000, -3), Some((41, 1279.0, 0.0)));
+        // Unknown selection falls back to the first stream
+        assert_eq!(stream_position(&streams, 7, 10, 10), Some((40, 10.0, 10.0)));
+        assert_eq!(stream_position(&[], 0, 10, 10), None);
+    }
+
+    #[test]
+    fn test_layout_position_spans_all_outputs() {
+        let streams = [stream(40, (0, 0), (1000, 500)), stream(41, (1000, 0), (1001, 501))];
+        // Top-left of the second output is just past the middle of the layout
+        assert_eq!(layout_position(&streams, 1, 0, 0, 2000), Some((1000, 0)));
+        assert_eq!(layout_position(&streams, 1, 1000, 500, 2000), Some((2000, 2000)));
+        // Without geometry there is nothing to map onto
+        assert_eq!(layout_position(&[stream(40, (0, 0), (0, 0))], 0, 5, 5, 2000), None);
+    }
+
+    #[tokio::test]
+    async fn test_block_user_input_is_unsupported() {
+        let handler = WaylandInputHandler::new().await.unwrap();
+        let err = handler.block_user_input().await.unwrap_err();
+        assert!(matches!(err, GhostLinkError::Input(InputError::Unsupported { .. })));
+        assert!(handler.unblock_user_input().await.is_ok());
+    }
+
+    /// Needs a live Wayland session: approve the portal dialog (or give the
+    /// user access to /dev/uinput) when it appears. Moves the pointer and
+    /// types into whatever window has focus.
+    #[cfg(feature = "wayland-input-tests")]
+    #[tokio::test]
+    async fn test_injects_into_live_session() {
+        use crate::capture::wayland::WaylandPortalCapturer;
+        use crate::capture::ScreenCapturer;
+
+        let mut capturer = WaylandPortalCapturer::new().await.unwrap();
+        capturer.initialize().await.unwrap();
+
+        let mut handler = WaylandInputHandler::new().await.unwrap();
+        handler.initialize().await.unwrap();
+        assert!(handler.is_healthy());
+
+        handler.handle_mouse_move(10, 10).await.unwrap();
+        handler.handle_mouse_button(MouseButton::Left, true).await.unwrap();
+        handler.handle_mouse_button(MouseButton::Left, false).await.unwrap();
+        handler.handle_mouse_scroll(0, -1).await.unwrap();
+        handler.handle_key_event(KeyCode::Shift, true).await.unwrap();
+        handler.handle_key_event(KeyCode::Shift, false).await.unwrap();
+        handler.handle_text_input("ghostlink").await.unwrap();
+
+        handler.cleanup().await.unwrap();
+        capturer.cleanup().await.unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use crate::error::{InputError, Result};
 use tracing::{debug, info, warn};
 
 use crate::input::{InputHandler, KeyCode, MouseButton};
@@ -126,11 +126,28 @@ impl X11InputHandler {
         let output = std::process::Command::new("xdotool")
             .args(&["mousemove", &format!("{}", x), &format!("{}", y)])
             .output()
-            .map_err(|e| anyhow::anyhow!("Failed to execute xdotool: {}", e))?;
+            .map_err(xdotool_error)?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow::anyhow!("xdotool mousemove failed: {}", stderr));
+            return Err(InputError::InjectionFailed { reason: format!("xdotool mousemove failed: {}", stderr) }.into());
+        }
+
+        Ok(())
+    }
+
+    /// Relative moves go through XTestFakeRelativeMotionEvent, so apps that
+    /// grab the pointer see motion rather than a warp
+    async fn xdotool_mouse_move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+        // `--` keeps negative deltas from being read as options
+        let output = std::process::Command::new("xdotool")
+            .args(&["mousemove_relative", "--", &format!("{}", dx), &format!("{}", dy)])
+            .output()
+            .map_err(xdotool_error)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(InputError::InjectionFailed { reason: format!("xdotool mousemove_relative failed: {}", stderr) }.into());
         }
 
         Ok(())
@@ -143,11 +160,11 @@ impl X11InputHandler {
         let output = std::process::Command::new("xdotool")
             .args(&[action, &format!("{}", button_num)])
             .output()
-            .map_err(|e| anyhow::anyhow!("Failed to execute xdotool: {}", e))?;
+            .map_err(xdotool_error)?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow::anyhow!("xdotool {} failed: {}", action, stderr));
+            return Err(InputError::InjectionFailed { reason: format!("xdotool {} failed: {}", action, stderr) }.into());
         }
 
         Ok(())
@@ -160,11 +177,11 @@ impl X11InputHandler {
             let output = std::process::Command::new("xdotool")
                 .args(&[action, &format!("0x{:x}", keysym)])
                 .output()
-                .map_err(|e| anyhow::anyhow!("Failed to execute xdotool: {}", e))?;
+                .map_err(xdotool_error)?;
 
             if !output.status.success() {
                 let stderr = String::from_utf8_lossy(&output.stderr);
-                return Err(anyhow::anyhow!("xdotool {} failed: {}", action, stderr));
+                return Err(InputError::InjectionFailed { reason: format!("xdotool {} failed: {}", action, stderr) }.into());
             }
         }
 
@@ -172,6 +189,15 @@ impl X11InputHandler {
     }
 }
 
+/// A missing xdotool binary is reported as such rather than as a failed injection
+fn xdotool_error(e: std::io::Error) -> crate::error::GhostLinkError {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        InputError::ToolMissing { tool: "xdotool".to_string() }.into()
+    } else {
+        InputError::InjectionFailed { reason: format!("Failed to execute xdotool: {}", e) }.into()
+    }
+}
+
 #[async_trait::async_trait]
 impl InputHandler for X11InputHandler {
     async fn initialize(&mut self) -> Result<()> {
@@ -190,7 +216,7 @@ impl InputHandler for X11InputHandler {
         if xdotool_check.is_err() || !xdotool_check.unwrap().status.success() {
             warn!("xdotool not found - X11 input simulation will be limited");
             self.is_healthy = false;
-            return Err(anyhow::anyhow!("xdotool not available for X11 input simulation"));
+            return Err(InputError::ToolMissing { tool: "xdotool".to_string() }.into());
         }
         
         self.is_healthy = true;
@@ -203,6 +229,15 @@ impl InputHandler for X11InputHandler {
         self.xdotool_mouse_move(x, y).await
     }
 
+    async fn handle_mouse_move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+        debug!("Moving mouse by ({}, {})", dx, dy);
+        self.xdotool_mouse_move_relative(dx, dy).await
+    }
+
+    fn supports_relative_motion(&self) -> bool {
+        true
+    }
+
     async fn handle_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()> {
         debug!("Mouse button {:?} {}", button, if pressed { "pressed" } else { "released" });
         self.xdotool_mouse_button(button, pressed).await
@@ -241,11 +276,11 @@ impl InputHandler for X11InputHandler {
         let output = std::process::Command::new("xdotool")
             .args(&["type", text])
             .output()
-            .map_err(|e| anyhow::anyhow!("Failed to execute xdotool type: {}", e))?;
+            .map_err(xdotool_error)?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow::anyhow!("xdotool type failed: {}", stderr));
+            return Err(InputError::InjectionFailed { reason: format!("xdotool type failed: {}", stderr) }.into());
         }
 
         Ok(())
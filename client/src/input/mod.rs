@@ -1,13 +1,16 @@
 #![allow(dead_code)]
 
-use anyhow::Result;
+use crate::error::{GhostLinkError, Result};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{debug, info};
 
 use crate::session::SessionType;
 
+pub mod keycodes;
+
 #[cfg(target_os = "linux")]
 pub mod linux;
 
@@ -22,14 +25,70 @@ pub use wayland_portal::{RemoteDesktopPortal, KeyState, ButtonState, Axis};
 #[cfg(target_os = "windows")]
 pub mod windows;
 
+#[cfg(target_os = "windows")]
+pub mod sas;
+
 #[cfg(target_os = "macos")]
 pub mod macos;
 
+pub mod pointer_mode;
+pub mod relative;
+
 /// Cross-platform input control
 pub struct InputController {
     controller: InputHandlerEnum,
     is_input_blocked: Arc<RwLock<bool>>,
     session_type: SessionType,
+    /// Resolution the technician's viewer is rendering at
+    viewer_resolution: Arc<RwLock<Option<(u32, u32)>>>,
+    /// Resolution of the captured display on this machine
+    screen_resolution: Arc<RwLock<Option<(u32, u32)>>>,
+    /// Whether pointer events are positions or movement
+    input_mode: Arc<RwLock<InputMode>>,
+    /// Validate events without injecting them, for training sessions and
+    /// the pre-flight
+    preview: Arc<AtomicBool>,
+    counters: Arc<InputCounters>,
+}
+
+/// Running totals of the events a controller handled
+#[derive(Debug, Default)]
+pub struct InputCounters {
+    received: AtomicU64,
+    injected: AtomicU64,
+    previewed: AtomicU64,
+}
+
+impl InputCounters {
+    pub fn snapshot(&self) -> InputStats {
+        InputStats {
+            received: self.received.load(Ordering::Relaxed),
+            injected: self.injected.load(Ordering::Relaxed),
+            previewed: self.previewed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Input events of a session, for the quality report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputStats {
+    /// Events that arrived while input was not blocked
+    pub received: u64,
+    /// Events passed to the platform handler
+    pub injected: u64,
+    /// Events validated in preview mode and not injected
+    pub previewed: u64,
+}
+
+/// What became of an input event
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputOutcome {
+    Injected,
+    /// Validated but not injected; the viewer gets it back as an
+    /// acknowledgment
+    Previewed(InputEvent),
+    /// Blocked, or from the other pointer mode
+    Ignored,
 }
 
 /// Enum to hold different input handler implementations
@@ -80,6 +139,38 @@ impl InputHandlerEnum {
     }
     
     /// Handle mouse button press/release
+    /// Handle relative mouse movement
+    pub async fn handle_mouse_move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+        match self {
+            #[cfg(target_os = "linux")]
+            InputHandlerEnum::WaylandInput(handler) => handler.handle_mouse_move_relative(dx, dy).await,
+            #[cfg(target_os = "linux")]
+            InputHandlerEnum::X11Input(handler) => handler.handle_mouse_move_relative(dx, dy).await,
+            #[cfg(target_os = "windows")]
+            InputHandlerEnum::WindowsInput(handler) => handler.handle_mouse_move_relative(dx, dy).await,
+            #[cfg(target_os = "macos")]
+            InputHandlerEnum::MacInput(handler) => handler.handle_mouse_move_relative(dx, dy).await,
+            #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+            InputHandlerEnum::Placeholder => Ok(()),
+        }
+    }
+    
+    /// Check if the handler can inject relative motion
+    pub fn supports_relative_motion(&self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            InputHandlerEnum::WaylandInput(handler) => handler.supports_relative_motion(),
+            #[cfg(target_os = "linux")]
+            InputHandlerEnum::X11Input(handler) => handler.supports_relative_motion(),
+            #[cfg(target_os = "windows")]
+            InputHandlerEnum::WindowsInput(handler) => handler.supports_relative_motion(),
+            #[cfg(target_os = "macos")]
+            InputHandlerEnum::MacInput(handler) => handler.supports_relative_motion(),
+            #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+            InputHandlerEnum::Placeholder => false,
+        }
+    }
+    
     pub async fn handle_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()> {
         match self {
             #[cfg(target_os = "linux")]
@@ -175,6 +266,22 @@ impl InputHandlerEnum {
         }
     }
     
+    /// Send the secure attention sequence (Ctrl+Alt+Del)
+    pub async fn send_secure_attention(&self) -> Result<()> {
+        match self {
+            #[cfg(target_os = "linux")]
+            InputHandlerEnum::WaylandInput(handler) => handler.send_secure_attention().await,
+            #[cfg(target_os = "linux")]
+            InputHandlerEnum::X11Input(handler) => handler.send_secure_attention().await,
+            #[cfg(target_os = "windows")]
+            InputHandlerEnum::WindowsInput(handler) => handler.send_secure_attention().await,
+            #[cfg(target_os = "macos")]
+            InputHandlerEnum::MacInput(handler) => handler.send_secure_attention().await,
+            #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+            InputHandlerEnum::Placeholder => Ok(()),
+        }
+    }
+    
     /// Check if input is currently blocked
     pub fn is_input_blocked(&self) -> bool {
         match self {
@@ -224,6 +331,65 @@ impl InputHandlerEnum {
     }
 }
 
+#[async_trait::async_trait]
+impl InputHandler for InputHandlerEnum {
+    async fn initialize(&mut self) -> Result<()> {
+        InputHandlerEnum::initialize(self).await
+    }
+
+    async fn handle_mouse_move(&self, x: i32, y: i32) -> Result<()> {
+        InputHandlerEnum::handle_mouse_move(self, x, y).await
+    }
+
+    async fn handle_mouse_move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+        InputHandlerEnum::handle_mouse_move_relative(self, dx, dy).await
+    }
+
+    fn supports_relative_motion(&self) -> bool {
+        InputHandlerEnum::supports_relative_motion(self)
+    }
+
+    async fn handle_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()> {
+        InputHandlerEnum::handle_mouse_button(self, button, pressed).await
+    }
+
+    async fn handle_mouse_scroll(&self, delta_x: i32, delta_y: i32) -> Result<()> {
+        InputHandlerEnum::handle_mouse_scroll(self, delta_x, delta_y).await
+    }
+
+    async fn handle_key_event(&self, key: KeyCode, pressed: bool) -> Result<()> {
+        InputHandlerEnum::handle_key_event(self, key, pressed).await
+    }
+
+    async fn handle_text_input(&self, text: &str) -> Result<()> {
+        InputHandlerEnum::handle_text_input(self, text).await
+    }
+
+    async fn block_user_input(&self) -> Result<()> {
+        InputHandlerEnum::block_user_input(self).await
+    }
+
+    async fn unblock_user_input(&self) -> Result<()> {
+        InputHandlerEnum::unblock_user_input(self).await
+    }
+
+    async fn send_secure_attention(&self) -> Result<()> {
+        InputHandlerEnum::send_secure_attention(self).await
+    }
+
+    fn is_input_blocked(&self) -> bool {
+        InputHandlerEnum::is_input_blocked(self)
+    }
+
+    fn is_healthy(&self) -> bool {
+        InputHandlerEnum::is_healthy(self)
+    }
+
+    async fn cleanup(&mut self) -> Result<()> {
+        InputHandlerEnum::cleanup(self).await
+    }
+}
+
 /// Platform-specific input handling trait
 #[async_trait::async_trait]
 pub trait InputHandler: Send + Sync {
@@ -233,6 +399,16 @@ pub trait InputHandler: Send + Sync {
     /// Handle mouse movement
     async fn handle_mouse_move(&self, x: i32, y: i32) -> Result<()>;
     
+    /// Handle relative mouse movement, in device pixels
+    async fn handle_mouse_move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+        relative::move_pointer_by(dx, dy)
+    }
+    
+    /// Check if `handle_mouse_move_relative` can inject motion
+    fn supports_relative_motion(&self) -> bool {
+        relative::is_supported()
+    }
+    
     /// Handle mouse button press/release
     async fn handle_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()>;
     
@@ -251,6 +427,25 @@ pub trait InputHandler: Send + Sync {
     /// Unblock user input
     async fn unblock_user_input(&self) -> Result<()>;
     
+    /// Send the secure attention sequence (Ctrl+Alt+Del).
+    ///
+    /// Windows reserves Ctrl+Alt+Del and ignores it when injected as keys,
+    /// so it has its own call; elsewhere there is no such sequence.
+    async fn send_secure_attention(&self) -> Result<()> {
+        #[cfg(target_os = "windows")]
+        {
+            sas::send_secure_attention()
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        {
+            Err(crate::error::GhostLinkError::Input(crate::error::InputError::Unsupported {
+                operation: "The secure attention sequence".to_string(),
+                platform: std::env::consts::OS.to_string(),
+            }))
+        }
+    }
+    
     /// Check if input is currently blocked
     fn is_input_blocked(&self) -> bool;
     
@@ -307,15 +502,29 @@ pub enum KeyCode {
     Raw(u32),
 }
 
+/// How remote pointer events are applied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMode {
+    /// `MouseMove` positions on the captured screen
+    #[default]
+    Absolute,
+    /// `MouseMoveRelative` movement, for apps that capture the cursor
+    Relative,
+}
+
 /// Input event from remote operator
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum InputEvent {
     MouseMove { x: i32, y: i32 },
+    MouseMoveRelative { dx: i32, dy: i32 },
     MouseButton { button: MouseButton, pressed: bool },
     MouseScroll { delta_x: i32, delta_y: i32 },
     KeyEvent { key: KeyCode, pressed: bool },
     TextInput { text: String },
+    /// Ctrl+Alt+Del, which can't be sent as key events
+    SecureAttention,
 }
 
 impl InputController {
@@ -327,6 +536,11 @@ impl InputController {
             controller,
             is_input_blocked: Arc::new(RwLock::new(false)),
             session_type,
+            viewer_resolution: Arc::new(RwLock::new(None)),
+            screen_resolution: Arc::new(RwLock::new(None)),
+            input_mode: Arc::new(RwLock::new(InputMode::Absolute)),
+            preview: Arc::new(AtomicBool::new(false)),
+            counters: Arc::new(InputCounters::default()),
         };
         
         input_controller.initialize().await?;
@@ -374,41 +588,75 @@ impl InputController {
     }
 
     /// Handle input event from remote operator
-    pub async fn handle_event(&self, event_data: &serde_json::Value) -> Result<()> {
+    pub async fn handle_event(&self, event_data: &serde_json::Value) -> Result<InputOutcome> {
         // Check if input is currently blocked
         let blocked_guard = self.is_input_blocked.read().await;
         if *blocked_guard {
             debug!("Input event ignored - input is blocked");
-            return Ok(());
+            return Ok(InputOutcome::Ignored);
         }
         drop(blocked_guard);
 
-        // Parse input event
-        let event: InputEvent = serde_json::from_value(event_data.clone())
-            .map_err(|e| anyhow::anyhow!("Failed to parse input event: {}", e))?;
+        let event = parse_input_event(event_data)?;
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
 
         debug!("Handling input event: {:?}", event);
 
-        // Dispatch to appropriate handler
-        match event {
-            InputEvent::MouseMove { x, y } => {
-                self.controller.handle_mouse_move(x, y).await?;
-            }
-            InputEvent::MouseButton { button, pressed } => {
-                self.controller.handle_mouse_button(button, pressed).await?;
-            }
-            InputEvent::MouseScroll { delta_x, delta_y } => {
-                self.controller.handle_mouse_scroll(delta_x, delta_y).await?;
-            }
-            InputEvent::KeyEvent { key, pressed } => {
-                self.controller.handle_key_event(key, pressed).await?;
-            }
-            InputEvent::TextInput { text } => {
-                self.controller.handle_text_input(&text).await?;
-            }
+        let mode = *self.input_mode.read().await;
+        if !accepts_in_mode(&event, mode) {
+            debug!("Input event ignored - session is in {:?} input mode", mode);
+            return Ok(InputOutcome::Ignored);
         }
 
-        Ok(())
+        let viewer = *self.viewer_resolution.read().await;
+        let screen = *self.screen_resolution.read().await;
+        let event = match (viewer, screen) {
+            (Some(viewer), Some(screen)) => scale_input_event(event, viewer, screen),
+            _ => event,
+        };
+
+        deliver_input_event(&self.controller, event, self.is_preview(), &self.counters).await
+    }
+
+    /// Turn preview mode on or off; events are still parsed, scaled and
+    /// counted, but never reach the platform handler
+    pub fn set_preview(&self, enabled: bool) {
+        if self.preview.swap(enabled, Ordering::Relaxed) != enabled {
+            info!("Input preview {}", if enabled { "on" } else { "off" });
+        }
+    }
+
+    pub fn is_preview(&self) -> bool {
+        self.preview.load(Ordering::Relaxed)
+    }
+
+    /// Events handled so far
+    pub fn stats(&self) -> InputStats {
+        self.counters.snapshot()
+    }
+
+    /// Set the viewer and captured screen resolutions used to scale pointer coordinates
+    pub async fn set_resolutions(&self, viewer: (u32, u32), screen: (u32, u32)) {
+        debug!("Input scaling: viewer {:?} -> screen {:?}", viewer, screen);
+        *self.viewer_resolution.write().await = Some(viewer);
+        *self.screen_resolution.write().await = Some(screen);
+    }
+
+    /// Switch between absolute and relative pointer input, returning the mode
+    /// now in effect; relative falls back to absolute where it can't be injected
+    pub async fn set_input_mode(&self, requested: InputMode) -> InputMode {
+        let mode = match requested {
+            InputMode::Relative if !self.controller.supports_relative_motion() => InputMode::Absolute,
+            mode => mode,
+        };
+        info!("Input mode: {:?} (requested {:?})", mode, requested);
+        *self.input_mode.write().await = mode;
+        mode
+    }
+
+    /// Current pointer input mode
+    pub async fn input_mode(&self) -> InputMode {
+        *self.input_mode.read().await
     }
 
     /// Block user input (for backstage sessions with screen blanking)
@@ -445,6 +693,26 @@ impl InputController {
     pub fn is_healthy(&self) -> bool {
         self.controller.is_healthy()
     }
+
+    /// Take over the scaling, pointer mode, preview, counters and input
+    /// blocking of the controller this one replaces
+    pub async fn inherit(&mut self, previous: &InputController) -> Result<()> {
+        if let (Some(viewer), Some(screen)) = (*previous.viewer_resolution.read().await, *previous.screen_resolution.read().await) {
+            self.set_resolutions(viewer, screen).await;
+        }
+        self.set_input_mode(previous.input_mode().await).await;
+        self.set_preview(previous.is_preview());
+        self.counters = Arc::clone(&previous.counters);
+        if previous.is_input_blocked().await {
+            self.block_user_input().await?;
+        }
+        Ok(())
+    }
+
+    /// Release the input handler's resources
+    pub async fn cleanup(&mut self) -> Result<()> {
+        self.controller.cleanup().await
+    }
 }
 
 impl Drop for InputController {
@@ -473,14 +741,80 @@ pub fn scale_coordinates(
     (scaled_x, scaled_y)
 }
 
-/// Helper function to translate key codes between platforms
+/// Parse an input event from a relay JSON payload
+pub fn parse_input_event(event_data: &serde_json::Value) -> Result<InputEvent> {
+    serde_json::from_value(event_data.clone())
+        .map_err(|e| GhostLinkError::Protocol(format!("Failed to parse input event: {}", e)))
+}
+
+/// Scale pointer coordinates from viewer space to screen space
+pub fn scale_input_event(event: InputEvent, viewer: (u32, u32), screen: (u32, u32)) -> InputEvent {
+    match event {
+        InputEvent::MouseMove { x, y } if viewer != screen && viewer.0 > 0 && viewer.1 > 0 => {
+            let (x, y) = scale_coordinates(x, y, viewer, screen);
+            InputEvent::MouseMove { x, y }
+        }
+        other => other,
+    }
+}
+
+/// Whether a pointer event belongs to the session's input mode. Positions
+/// arriving in relative mode (and deltas in absolute mode) were sent before
+/// the viewer saw the switch and are dropped rather than fighting it.
+pub fn accepts_in_mode(event: &InputEvent, mode: InputMode) -> bool {
+    match event {
+        InputEvent::MouseMove { .. } => mode == InputMode::Absolute,
+        InputEvent::MouseMoveRelative { .. } => mode == InputMode::Relative,
+        _ => true,
+    }
+}
+
+/// Dispatch a parsed input event to a platform input handler
+pub async fn dispatch_input_event<H: InputHandler + ?Sized>(handler: &H, event: InputEvent) -> Result<()> {
+    match event {
+        InputEvent::MouseMove { x, y } => handler.handle_mouse_move(x, y).await,
+        InputEvent::MouseMoveRelative { dx, dy } => handler.handle_mouse_move_relative(dx, dy).await,
+        InputEvent::MouseButton { button, pressed } => handler.handle_mouse_button(button, pressed).await,
+        InputEvent::MouseScroll { delta_x, delta_y } => handler.handle_mouse_scroll(delta_x, delta_y).await,
+        InputEvent::KeyEvent { key, pressed } => handler.handle_key_event(key, pressed).await,
+        InputEvent::TextInput { text } => handler.handle_text_input(&text).await,
+        InputEvent::SecureAttention => handler.send_secure_attention().await,
+    }
+}
+
+/// Dispatch `event` to `handler`, or only count it while previewing
+pub async fn deliver_input_event<H: InputHandler + ?Sized>(
+    handler: &H,
+    event: InputEvent,
+    preview: bool,
+    counters: &InputCounters,
+) -> Result<InputOutcome> {
+    if preview {
+        counters.previewed.fetch_add(1, Ordering::Relaxed);
+        return Ok(InputOutcome::Previewed(event));
+    }
+    dispatch_input_event(handler, event).await?;
+    counters.injected.fetch_add(1, Ordering::Relaxed);
+    Ok(InputOutcome::Injected)
+}
+
+/// Native key code for the platform this agent runs on: an X11 keysym on
+/// Linux, a virtual-key code on Windows, a `CGKeyCode` on macOS. `Raw` codes
+/// are passed through as already native.
 pub fn translate_key_code(key: KeyCode) -> u32 {
-    match key {
-        // This would be implemented per-platform
-        // For now, return a placeholder
-        KeyCode::Raw(code) => code,
-        _ => 0, // TODO: Implement proper key code translation
+    if let KeyCode::Raw(code) = key {
+        return code;
     }
+
+    #[cfg(target_os = "windows")]
+    let code = keycodes::windows_vk(key).map(u32::from);
+    #[cfg(target_os = "macos")]
+    let code = keycodes::macos_keycode(key).map(u32::from);
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let code = keycodes::x11_keysym(key);
+
+    // Every non-Raw key has a row in the table
+    code.unwrap_or_default()
 }
 
 // ===== New Native Input System =====
@@ -495,3 +829,152 @@ pub mod input_service;
 
 // Re-exports for convenience
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Input handler that records the calls it receives
+    #[derive(Default)]
+    struct MockInputHandler {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl MockInputHandler {
+        fn record(&self, call: String) -> Result<()> {
+            self.calls.lock().unwrap().push(call);
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl InputHandler for MockInputHandler {
+        async fn initialize(&mut self) -> Result<()> { Ok(()) }
+        async fn handle_mouse_move(&self, x: i32, y: i32) -> Result<()> {
+            self.record(format!("move {} {}", x, y))
+        }
+        async fn handle_mouse_move_relative(&self, dx: i32, dy: i32) -> Result<()> {
+            self.record(format!("move by {} {}", dx, dy))
+        }
+        async fn handle_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()> {
+            self.record(format!("button {:?} {}", button, pressed))
+        }
+        async fn handle_mouse_scroll(&self, delta_x: i32, delta_y: i32) -> Result<()> {
+            self.record(format!("scroll {} {}", delta_x, delta_y))
+        }
+        async fn handle_key_event(&self, key: KeyCode, pressed: bool) -> Result<()> {
+            self.record(format!("key {:?} {}", key, pressed))
+        }
+        async fn handle_text_input(&self, text: &str) -> Result<()> {
+            self.record(format!("text {}", text))
+        }
+        async fn block_user_input(&self) -> Result<()> { Ok(()) }
+        async fn unblock_user_input(&self) -> Result<()> { Ok(()) }
+        async fn send_secure_attention(&self) -> Result<()> {
+            self.record("secure attention".to_string())
+        }
+        fn is_input_blocked(&self) -> bool { false }
+        fn is_healthy(&self) -> bool { true }
+        async fn cleanup(&mut self) -> Result<()> { Ok(()) }
+    }
+
+    #[tokio::test]
+    async fn test_json_events_reach_handler() {
+        let handler = MockInputHandler::default();
+        let payloads = [
+            serde_json::json!({"type": "MouseMove", "x": 10, "y": 20}),
+            serde_json::json!({"type": "MouseMoveRelative", "dx": -3, "dy": 4}),
+            serde_json::json!({"type": "MouseButton", "button": "Left", "pressed": true}),
+            serde_json::json!({"type": "MouseScroll", "delta_x": 0, "delta_y": -3}),
+            serde_json::json!({"type": "KeyEvent", "key": "A", "pressed": false}),
+            serde_json::json!({"type": "TextInput", "text": "hello"}),
+            serde_json::json!({"type": "SecureAttention"}),
+        ];
+
+        for payload in &payloads {
+            let event = parse_input_event(payload).unwrap();
+            dispatch_input_event(&handler, event).await.unwrap();
+        }
+
+        let calls = handler.calls.lock().unwrap();
+        assert_eq!(*calls, vec![
+            "move 10 20",
+            "move by -3 4",
+            "button Left true",
+            "scroll 0 -3",
+            "key A false",
+            "text hello",
+            "secure attention",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_preview_never_reaches_handler() {
+        let handler = MockInputHandler::default();
+        let counters = InputCounters::default();
+        let click = InputEvent::MouseButton { button: MouseButton::Left, pressed: true };
+
+        let outcome = deliver_input_event(&handler, click.clone(), true, &counters).await.unwrap();
+        assert_eq!(outcome, InputOutcome::Previewed(click.clone()));
+        assert!(handler.calls.lock().unwrap().is_empty());
+
+        let outcome = deliver_input_event(&handler, click, false, &counters).await.unwrap();
+        assert_eq!(outcome, InputOutcome::Injected);
+        assert_eq!(*handler.calls.lock().unwrap(), vec!["button Left true"]);
+        assert_eq!(counters.snapshot(), InputStats { received: 0, injected: 1, previewed: 1 });
+    }
+
+    #[test]
+    fn test_translate_key_code() {
+        assert_eq!(translate_key_code(KeyCode::Raw(1234)), 1234);
+        #[cfg(target_os = "linux")]
+        assert_eq!(translate_key_code(KeyCode::Enter), 0xff0d);
+        #[cfg(target_os = "windows")]
+        assert_eq!(translate_key_code(KeyCode::Enter), 0x0d);
+        #[cfg(target_os = "macos")]
+        assert_eq!(translate_key_code(KeyCode::Enter), 0x24);
+    }
+
+    #[test]
+    fn test_pointer_events_follow_input_mode() {
+        let absolute = InputEvent::MouseMove { x: 1, y: 2 };
+        let relative = InputEvent::MouseMoveRelative { dx: 1, dy: 2 };
+        let button = InputEvent::MouseButton { button: MouseButton::Left, pressed: true };
+
+        assert!(accepts_in_mode(&absolute, InputMode::Absolute));
+        assert!(!accepts_in_mode(&relative, InputMode::Absolute));
+        assert!(!accepts_in_mode(&absolute, InputMode::Relative));
+        assert!(accepts_in_mode(&relative, InputMode::Relative));
+        // Buttons, scrolling and keys work the same in both modes
+        assert!(accepts_in_mode(&button, InputMode::Absolute));
+        assert!(accepts_in_mode(&button, InputMode::Relative));
+
+        // Deltas are never scaled to the screen
+        assert!(matches!(
+            scale_input_event(relative, (1280, 720), (2560, 1440)),
+            InputEvent::MouseMoveRelative { dx: 1, dy: 2 }
+        ));
+        assert_eq!(serde_json::to_value(InputMode::Relative).unwrap(), "relative");
+    }
+
+    #[test]
+    fn test_invalid_payload_is_rejected() {
+        assert!(parse_input_event(&serde_json::json!({"type": "Teleport"})).is_err());
+    }
+
+    #[test]
+    fn test_mouse_move_is_scaled_to_screen() {
+        let event = InputEvent::MouseMove { x: 640, y: 360 };
+        match scale_input_event(event, (1280, 720), (2560, 1440)) {
+            InputEvent::MouseMove { x, y } => assert_eq!((x, y), (1280, 720)),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // Non-pointer events pass through untouched
+        let event = InputEvent::MouseScroll { delta_x: 1, delta_y: 2 };
+        assert!(matches!(
+            scale_input_event(event, (1280, 720), (2560, 1440)),
+            InputEvent::MouseScroll { delta_x: 1, delta_y: 2 }
+        ));
+    }
+}
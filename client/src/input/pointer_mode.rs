@@ -0,0 +1,107 @@
+//! Noticing when the focused app captures the pointer
+//!
+//! Games, CAD viewports and VM consoles grab the cursor: they hide it and warp
+//! it back to a fixed point after every move, reading only the movement. From
+//! the captured screen that looks like a hidden cursor that keeps returning to
+//! the same spot. A hidden cursor that stays put (a video player) or follows
+//! the pointer around does not count. When a grab shows up, or a cursor the
+//! detector suggested relative input for becomes visible again, the agent
+//! hints the viewer to switch modes.
+
+use crate::capture::cursor::CursorUpdate;
+
+use super::InputMode;
+
+/// Returns to the anchor point before a grab is assumed
+const CAPTURE_RETURNS: u32 = 3;
+
+/// Follows cursor updates and reports when the right input mode changes
+#[derive(Debug, Default)]
+pub struct PointerCaptureDetector {
+    /// Where a hidden cursor keeps coming back to
+    anchor: Option<(i32, i32)>,
+    /// Hidden cursor position in the previous update
+    last: Option<(i32, i32)>,
+    returns: u32,
+    /// Mode last suggested to the viewer
+    suggested: Option<InputMode>,
+}
+
+impl PointerCaptureDetector {
+    /// Feed one cursor update; returns the mode to suggest when it changes
+    pub fn observe(&mut self, cursor: &CursorUpdate) -> Option<InputMode> {
+        let position = (cursor.x, cursor.y);
+        if cursor.visible {
+            self.anchor = None;
+            self.last = None;
+            self.returns = 0;
+            // Only take back a suggestion that was made
+            if self.suggested == Some(InputMode::Relative) {
+                self.suggested = Some(InputMode::Absolute);
+                return self.suggested;
+            }
+            return None;
+        }
+
+        match self.anchor {
+            Some(anchor) if anchor == position => {
+                if self.last != Some(anchor) {
+                    self.returns += 1;
+                }
+            }
+            // Moving away from the anchor and back is a warp; moving from
+            // one spot to another is a hidden cursor that is following along
+            Some(anchor) if self.last != Some(anchor) => {
+                self.anchor = Some(position);
+                self.returns = 0;
+            }
+            Some(_) => {}
+            None => self.anchor = Some(position),
+        }
+        self.last = Some(position);
+
+        if self.returns >= CAPTURE_RETURNS && self.suggested != Some(InputMode::Relative) {
+            self.suggested = Some(InputMode::Relative);
+            return self.suggested;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(x: i32, y: i32, visible: bool) -> CursorUpdate {
+        CursorUpdate { x, y, visible, shape: None }
+    }
+
+    #[test]
+    fn test_grabbed_pointer_suggests_relative_then_absolute() {
+        let mut detector = PointerCaptureDetector::default();
+        // A game hides the cursor and warps it back to the centre
+        let updates = [(960, 540), (975, 548), (960, 540), (940, 530), (960, 540), (962, 560)];
+        for (x, y) in updates {
+            assert_eq!(detector.observe(&cursor(x, y, false)), None);
+        }
+        assert_eq!(detector.observe(&cursor(960, 540, false)), Some(InputMode::Relative));
+        // Suggested once, not on every warp
+        assert_eq!(detector.observe(&cursor(990, 500, false)), None);
+        assert_eq!(detector.observe(&cursor(960, 540, false)), None);
+
+        // Back at the menu the cursor shows again
+        assert_eq!(detector.observe(&cursor(700, 300, true)), Some(InputMode::Absolute));
+        assert_eq!(detector.observe(&cursor(710, 300, true)), None);
+    }
+
+    #[test]
+    fn test_hidden_cursor_that_moves_is_not_a_grab() {
+        let mut detector = PointerCaptureDetector::default();
+        // A hidden cursor following the pointer never comes back
+        for x in (0..200).step_by(10) {
+            assert_eq!(detector.observe(&cursor(x, 100, false)), None);
+        }
+        // Visible cursors never need taking back without a suggestion
+        assert_eq!(detector.observe(&cursor(50, 50, true)), None);
+    }
+}
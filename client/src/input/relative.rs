@@ -0,0 +1,114 @@
+//! Relative pointer motion where there is no dedicated handler for it
+//!
+//! Apps that capture the cursor (games, CAD viewports, VM consoles) read raw
+//! movement rather than positions, and warp the cursor back to a fixed point.
+//! Absolute moves fight that warp, so relative input mode injects the
+//! viewer's deltas as device motion instead: `SendInput` with
+//! `MOUSEEVENTF_MOVE` (no `MOUSEEVENTF_ABSOLUTE`) on Windows, and a mouse
+//! moved event carrying the delta fields on macOS. Linux handlers implement
+//! it themselves (XTest, the RemoteDesktop portal or uinput).
+
+use crate::error::Result;
+
+/// Move the pointer by a delta in device pixels
+#[cfg(target_os = "windows")]
+pub fn move_pointer_by(dx: i32, dy: i32) -> Result<()> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::{
+        SendInput, INPUT, INPUT_0, INPUT_MOUSE, MOUSEEVENTF_MOVE, MOUSEINPUT,
+    };
+
+    use crate::error::{GhostLinkError, InputError};
+
+    // Relative moves go through the user's pointer speed and acceleration
+    // settings, as a physical mouse's would
+    let input = INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx,
+                dy,
+                mouseData: 0,
+                dwFlags: MOUSEEVENTF_MOVE,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    };
+    let sent = unsafe { SendInput(&[input], std::mem::size_of::<INPUT>() as i32) };
+    if sent != 1 {
+        // UIPI blocks injection into higher-integrity windows
+        return Err(GhostLinkError::Input(InputError::InputBlocked));
+    }
+    Ok(())
+}
+
+/// Move the pointer by a delta in device pixels
+#[cfg(target_os = "macos")]
+pub fn move_pointer_by(dx: i32, dy: i32) -> Result<()> {
+    use std::ffi::c_void;
+
+    use crate::error::{GhostLinkError, InputError};
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CGPoint {
+        x: f64,
+        y: f64,
+    }
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGEventCreate(source: *const c_void) -> *mut c_void;
+        fn CGEventGetLocation(event: *mut c_void) -> CGPoint;
+        fn CGEventCreateMouseEvent(source: *const c_void, kind: u32, location: CGPoint, button: u32) -> *mut c_void;
+        fn CGEventSetIntegerValueField(event: *mut c_void, field: u32, value: i64);
+        fn CGEventPost(tap: u32, event: *mut c_void);
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFRelease(object: *const c_void);
+    }
+
+    const MOUSE_MOVED: u32 = 5;
+    const MOUSE_EVENT_DELTA_X: u32 = 4;
+    const MOUSE_EVENT_DELTA_Y: u32 = 5;
+    const HID_EVENT_TAP: u32 = 0;
+
+    unsafe {
+        // A null event reports where the cursor is now
+        let current = CGEventCreate(std::ptr::null());
+        if current.is_null() {
+            return Err(GhostLinkError::Input(InputError::NotInitialized));
+        }
+        let location = CGEventGetLocation(current);
+        CFRelease(current);
+
+        // Apps with the cursor captured read the delta fields; the location
+        // moves along for everyone else
+        let target = CGPoint { x: location.x + dx as f64, y: location.y + dy as f64 };
+        let event = CGEventCreateMouseEvent(std::ptr::null(), MOUSE_MOVED, target, 0);
+        if event.is_null() {
+            return Err(GhostLinkError::Input(InputError::InvalidCoordinates { x: dx, y: dy }));
+        }
+        CGEventSetIntegerValueField(event, MOUSE_EVENT_DELTA_X, dx as i64);
+        CGEventSetIntegerValueField(event, MOUSE_EVENT_DELTA_Y, dy as i64);
+        CGEventPost(HID_EVENT_TAP, event);
+        CFRelease(event);
+    }
+    Ok(())
+}
+
+/// Move the pointer by a delta in device pixels
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub fn move_pointer_by(_dx: i32, _dy: i32) -> Result<()> {
+    Err(crate::error::GhostLinkError::Input(crate::error::InputError::Unsupported {
+        operation: "Relative pointer motion".to_string(),
+        platform: std::env::consts::OS.to_string(),
+    }))
+}
+
+/// Whether `move_pointer_by` can inject motion on this platform
+pub fn is_supported() -> bool {
+    cfg!(any(target_os = "windows", target_os = "macos"))
+}
@@ -0,0 +1,59 @@
+//! Secure attention sequence (Ctrl+Alt+Del) on Windows
+//!
+//! Windows never acts on an injected Ctrl+Alt+Del; the sequence has to come
+//! from `SendSAS` in sas.dll. That only works from a service running as
+//! LocalSystem, and only where the "Disable or enable software Secure
+//! Attention Sequence" policy (`SoftwareSASGeneration`) allows services to
+//! generate it. The agent service runs as LocalSystem; the policy is left to
+//! the administrator, and its absence is reported rather than changed.
+
+use windows::core::w;
+use windows::Win32::Foundation::ERROR_SUCCESS;
+use windows::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD};
+
+use crate::error::{GhostLinkError, InputError, Result};
+
+#[link(name = "sas")]
+extern "system" {
+    fn SendSAS(as_user: i32);
+}
+
+/// `SoftwareSASGeneration` values that let services send the sequence
+const SAS_SERVICES: u32 = 1;
+const SAS_SERVICES_AND_EASE_OF_ACCESS: u32 = 3;
+
+/// Read the `SoftwareSASGeneration` policy, if set
+fn software_sas_policy() -> Option<u32> {
+    let mut value: u32 = 0;
+    let mut size = std::mem::size_of::<u32>() as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            w!("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Policies\\System"),
+            w!("SoftwareSASGeneration"),
+            RRF_RT_REG_DWORD,
+            None,
+            Some(&mut value as *mut u32 as *mut _),
+            Some(&mut size),
+        )
+    };
+    (status == ERROR_SUCCESS).then_some(value)
+}
+
+/// Send Ctrl+Alt+Del to the console session
+pub fn send_secure_attention() -> Result<()> {
+    match software_sas_policy() {
+        Some(SAS_SERVICES) | Some(SAS_SERVICES_AND_EASE_OF_ACCESS) => {}
+        _ => {
+            return Err(GhostLinkError::Input(InputError::MethodUnavailable {
+                method: "SendSAS (enable the \"Disable or enable software Secure Attention Sequence\" \
+                         policy for services, SoftwareSASGeneration = 1)".to_string(),
+            }));
+        }
+    }
+
+    // FALSE: the caller is a service, not the interactive user
+    unsafe { SendSAS(0) };
+    tracing::info!("Sent secure attention sequence");
+    Ok(())
+}
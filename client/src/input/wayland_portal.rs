@@ -3,8 +3,10 @@
 //! This module provides input injection (keyboard, mouse) for Wayland compositors
 //! using the org.freedesktop.portal.RemoteDesktop portal interface.
 //!
-//! The RemoteDesktop portal is the only sanctioned way to inject input on Wayland,
-//! and it requires a valid ScreenCast session to be active first.
+//! The RemoteDesktop portal is the only sanctioned way to inject input on Wayland.
+//! The session is created by the screen capturer (see
+//! `capture::wayland::portal`), which asks for screen and input access in one
+//! dialog; this module only sends input into it.
 //!
 //! Reference: https://flatpak.github.io/xdg-desktop-portal/#gdbus-org.freedesktop.portal.RemoteDesktop
 
@@ -20,7 +22,7 @@ use dbus::Message;
 use tracing::{debug, info};
 
 use crate::error::{GhostLinkError, InputError, Result};
-use crate::capture::wayland::portal::PortalSession;
+use crate::capture::wayland::portal::SharedPortalSession;
 
 const PORTAL_BUS: &str = "org.freedesktop.portal.Desktop";
 const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
@@ -82,29 +84,20 @@ pub struct RemoteDesktopPortal {
 }
 
 impl RemoteDesktopPortal {
-    /// Create a new RemoteDesktop portal client
-    pub fn new() -> Result<Self> {
-        info!("Initializing RemoteDesktop Portal for input injection");
-
-        let conn = SyncConnection::new_session().map_err(|e| {
-            GhostLinkError::Input(InputError::MethodUnavailable {
-                method: format!("DBus session connection: {}", e),
-            })
-        })?;
-
-        Ok(Self {
-            conn: Arc::new(conn),
-            session: None,
-        })
-    }
-
-    /// Connect to an existing ScreenCast session for input injection
-    /// The RemoteDesktop portal requires an active ScreenCast session
-    pub fn connect_to_session(&mut self, portal_session: &PortalSession) -> Result<()> {
-        info!("Connecting RemoteDesktop to existing ScreenCast session");
-
-        // Get available devices - default to keyboard + pointer
-        let available_devices = DeviceType::Keyboard as u32 | DeviceType::Pointer as u32;
+    /// Attach to the capturer's remote desktop session
+    ///
+    /// The RemoteDesktop portal only accepts input for a session it started,
+    /// over the connection that created it, so this borrows both from the
+    /// shared capture session.
+    pub fn connect(portal_session: &SharedPortalSession) -> Result<Self> {
+        info!("Connecting RemoteDesktop input to the capture session");
+
+        let available_devices = portal_session.devices;
+        if available_devices & (DeviceType::Keyboard as u32 | DeviceType::Pointer as u32) == 0 {
+            return Err(GhostLinkError::Input(InputError::MethodUnavailable {
+                method: "RemoteDesktop portal (no keyboard or pointer access was granted)".to_string(),
+            }));
+        }
 
         info!("RemoteDesktop devices available: keyboard={}, pointer={}, touch={}",
             (available_devices & DeviceType::Keyboard as u32) != 0,
@@ -112,17 +105,23 @@ impl RemoteDesktopPortal {
             (available_devices & DeviceType::TouchScreen as u32) != 0,
         );
 
-        self.session = Some(RemoteDesktopSession {
-            session_path: portal_session.session_path.clone(),
-            available_devices,
-            is_active: true,
-        });
+        Ok(Self {
+            conn: portal_session.conn.clone(),
+            session: Some(RemoteDesktopSession {
+                session_path: portal_session.session_path.clone(),
+                available_devices,
+                is_active: true,
+            }),
+        })
+    }
 
-        Ok(())
+    /// Path of the portal session input is sent to
+    pub fn session_path(&self) -> Option<&dbus::Path<'static>> {
+        self.session.as_ref().map(|s| &s.session_path)
     }
 
-    /// Send a keyboard key event
-    pub fn notify_keyboard_keycode(&self, keycode: u32, state: KeyState) -> Result<()> {
+    /// The active session, or an error if input can't be sent
+    fn active_session(&self) -> Result<&RemoteDesktopSession> {
         let session = self.session.as_ref().ok_or_else(|| {
             GhostLinkError::Input(InputError::MethodUnavailable {
                 method: "No active RemoteDesktop session".to_string(),
@@ -133,197 +132,113 @@ impl RemoteDesktopPortal {
             return Err(GhostLinkError::Input(InputError::InputBlocked));
         }
 
-        debug!("Sending keyboard keycode {} state {:?}", keycode, state);
+        Ok(session)
+    }
 
-        // Build options dict
+    /// Build a Notify* call for the session
+    fn notify_call(&self, method: &str, session: &RemoteDesktopSession) -> Result<Message> {
         let options: PropMap = HashMap::new();
+        Ok(Message::new_method_call(PORTAL_BUS, PORTAL_PATH, REMOTE_DESKTOP_IFACE, method)
+            .map_err(|e| GhostLinkError::Other(format!("Failed to create message: {}", e)))?
+            .append2(&session.session_path, &options))
+    }
+
+    /// Queue a message and push it out. Nothing else drives this connection
+    /// once the capture session is up, so it has to be flushed here.
+    fn send(&self, msg: Message) -> std::result::Result<(), ()> {
+        self.conn.channel().send(msg)?;
+        self.conn.channel().flush();
+        Ok(())
+    }
+
+    /// Send a keyboard key event
+    pub fn notify_keyboard_keycode(&self, keycode: u32, state: KeyState) -> Result<()> {
+        let session = self.active_session()?;
+
+        debug!("Sending keyboard keycode {} state {:?}", keycode, state);
 
         // Call NotifyKeyboardKeycode(session_handle, options, keycode, state)
-        let msg = Message::new_method_call(
-            PORTAL_BUS,
-            PORTAL_PATH,
-            REMOTE_DESKTOP_IFACE,
-            "NotifyKeyboardKeycode",
-        )
-        .map_err(|e| GhostLinkError::Other(format!("Failed to create message: {}", e)))?
-        .append2(&session.session_path, &options)
-        .append2(keycode as i32, state as u32);
-
-        self.conn.channel().send(msg).map_err(|_| {
+        let msg = self.notify_call("NotifyKeyboardKeycode", session)?
+            .append2(keycode as i32, state as u32);
+
+        self.send(msg).map_err(|_| {
             GhostLinkError::Input(InputError::KeyMappingFailed {
                 key: format!("keycode {}", keycode),
             })
-        })?;
-
-        Ok(())
+        })
     }
 
     /// Send a keyboard key event using keysym (for Unicode/special keys)
     pub fn notify_keyboard_keysym(&self, keysym: u32, state: KeyState) -> Result<()> {
-        let session = self.session.as_ref().ok_or_else(|| {
-            GhostLinkError::Input(InputError::MethodUnavailable {
-                method: "No active RemoteDesktop session".to_string(),
-            })
-        })?;
-
-        if !session.is_active {
-            return Err(GhostLinkError::Input(InputError::InputBlocked));
-        }
+        let session = self.active_session()?;
 
-        debug!("Sending keyboard keysym {} state {:?}", keysym, state);
+        debug!("Sending keyboard keysym {:#x} state {:?}", keysym, state);
 
-        let options: PropMap = HashMap::new();
+        let msg = self.notify_call("NotifyKeyboardKeysym", session)?
+            .append2(keysym as i32, state as u32);
 
-        let msg = Message::new_method_call(
-            PORTAL_BUS,
-            PORTAL_PATH,
-            REMOTE_DESKTOP_IFACE,
-            "NotifyKeyboardKeysym",
-        )
-        .map_err(|e| GhostLinkError::Other(format!("Failed to create message: {}", e)))?
-        .append2(&session.session_path, &options)
-        .append2(keysym as i32, state as u32);
-
-        self.conn.channel().send(msg).map_err(|_| {
+        self.send(msg).map_err(|_| {
             GhostLinkError::Input(InputError::KeyMappingFailed {
-                key: format!("keysym {}", keysym),
+                key: format!("keysym {:#x}", keysym),
             })
-        })?;
-
-        Ok(())
+        })
     }
 
-    /// Send absolute pointer motion
+    /// Send absolute pointer motion, in the coordinate space of a stream
     pub fn notify_pointer_motion_absolute(&self, x: f64, y: f64, stream_id: u32) -> Result<()> {
-        let session = self.session.as_ref().ok_or_else(|| {
-            GhostLinkError::Input(InputError::MethodUnavailable {
-                method: "No active RemoteDesktop session".to_string(),
-            })
-        })?;
-
-        if !session.is_active {
-            return Err(GhostLinkError::Input(InputError::InputBlocked));
-        }
+        let session = self.active_session()?;
 
         debug!("Sending pointer motion absolute ({}, {}) stream {}", x, y, stream_id);
 
-        let options: PropMap = HashMap::new();
+        let msg = self.notify_call("NotifyPointerMotionAbsolute", session)?
+            .append3(stream_id, x, y);
 
-        let msg = Message::new_method_call(
-            PORTAL_BUS,
-            PORTAL_PATH,
-            REMOTE_DESKTOP_IFACE,
-            "NotifyPointerMotionAbsolute",
-        )
-        .map_err(|e| GhostLinkError::Other(format!("Failed to create message: {}", e)))?
-        .append2(&session.session_path, &options)
-        .append3(stream_id, x, y);
-
-        self.conn.channel().send(msg).map_err(|_| {
+        self.send(msg).map_err(|_| {
             GhostLinkError::Input(InputError::InvalidCoordinates { x: x as i32, y: y as i32 })
-        })?;
-
-        Ok(())
+        })
     }
 
     /// Send relative pointer motion
     pub fn notify_pointer_motion(&self, dx: f64, dy: f64) -> Result<()> {
-        let session = self.session.as_ref().ok_or_else(|| {
-            GhostLinkError::Input(InputError::MethodUnavailable {
-                method: "No active RemoteDesktop session".to_string(),
-            })
-        })?;
-
-        if !session.is_active {
-            return Err(GhostLinkError::Input(InputError::InputBlocked));
-        }
+        let session = self.active_session()?;
 
         debug!("Sending pointer motion relative ({}, {})", dx, dy);
 
-        let options: PropMap = HashMap::new();
+        let msg = self.notify_call("NotifyPointerMotion", session)?
+            .append2(dx, dy);
 
-        let msg = Message::new_method_call(
-            PORTAL_BUS,
-            PORTAL_PATH,
-            REMOTE_DESKTOP_IFACE,
-            "NotifyPointerMotion",
-        )
-        .map_err(|e| GhostLinkError::Other(format!("Failed to create message: {}", e)))?
-        .append2(&session.session_path, &options)
-        .append2(dx, dy);
-
-        self.conn.channel().send(msg).map_err(|_| {
+        self.send(msg).map_err(|_| {
             GhostLinkError::Other("Failed to send pointer motion".to_string())
-        })?;
-
-        Ok(())
+        })
     }
 
     /// Send pointer button event
     pub fn notify_pointer_button(&self, button: i32, state: ButtonState) -> Result<()> {
-        let session = self.session.as_ref().ok_or_else(|| {
-            GhostLinkError::Input(InputError::MethodUnavailable {
-                method: "No active RemoteDesktop session".to_string(),
-            })
-        })?;
-
-        if !session.is_active {
-            return Err(GhostLinkError::Input(InputError::InputBlocked));
-        }
+        let session = self.active_session()?;
 
         debug!("Sending pointer button {} state {:?}", button, state);
 
-        let options: PropMap = HashMap::new();
+        let msg = self.notify_call("NotifyPointerButton", session)?
+            .append2(button, state as u32);
 
-        let msg = Message::new_method_call(
-            PORTAL_BUS,
-            PORTAL_PATH,
-            REMOTE_DESKTOP_IFACE,
-            "NotifyPointerButton",
-        )
-        .map_err(|e| GhostLinkError::Other(format!("Failed to create message: {}", e)))?
-        .append2(&session.session_path, &options)
-        .append2(button, state as u32);
-
-        self.conn.channel().send(msg).map_err(|_| {
+        self.send(msg).map_err(|_| {
             GhostLinkError::Other("Failed to send pointer button".to_string())
-        })?;
-
-        Ok(())
+        })
     }
 
-    /// Send scroll axis event
+    /// Send scroll axis event; positive steps scroll down or right
     pub fn notify_pointer_axis(&self, axis: Axis, steps: i32) -> Result<()> {
-        let session = self.session.as_ref().ok_or_else(|| {
-            GhostLinkError::Input(InputError::MethodUnavailable {
-                method: "No active RemoteDesktop session".to_string(),
-            })
-        })?;
-
-        if !session.is_active {
-            return Err(GhostLinkError::Input(InputError::InputBlocked));
-        }
+        let session = self.active_session()?;
 
         debug!("Sending pointer axis {:?} steps {}", axis, steps);
 
-        let options: PropMap = HashMap::new();
-
         // NotifyPointerAxisDiscrete for scroll wheel
-        let msg = Message::new_method_call(
-            PORTAL_BUS,
-            PORTAL_PATH,
-            REMOTE_DESKTOP_IFACE,
-            "NotifyPointerAxisDiscrete",
-        )
-        .map_err(|e| GhostLinkError::Other(format!("Failed to create message: {}", e)))?
-        .append2(&session.session_path, &options)
-        .append2(axis as u32, steps);
-
-        self.conn.channel().send(msg).map_err(|_| {
-            GhostLinkError::Other("Failed to send pointer axis".to_string())
-        })?;
+        let msg = self.notify_call("NotifyPointerAxisDiscrete", session)?
+            .append2(axis as u32, steps);
 
-        Ok(())
+        self.send(msg).map_err(|_| {
+            GhostLinkError::Other("Failed to send pointer axis".to_string())
+        })
     }
 
     /// Check if input injection is available
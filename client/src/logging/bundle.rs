@@ -0,0 +1,264 @@
+//! Diagnostics bundles for `logs upload`
+//!
+//! A bundle is a zip of the log files written to within the requested
+//! window, the newest crash report when asked for, and a `manifest.json`
+//! describing the device. Uploads are signed with the enrollment key, as
+//! agents have no user token to present.
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use url::Url;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use super::{CRASH_PREFIX, LOG_FILE};
+use crate::config::ClientConfig;
+
+/// Unix time the upload was signed at
+pub const TIMESTAMP_HEADER: &str = "X-Ghostlink-Timestamp";
+/// Base64 Ed25519 signature over the agent ID, timestamp and bundle SHA-256
+pub const SIGNATURE_HEADER: &str = "X-Ghostlink-Signature";
+
+/// Describes the device and contents of a bundle
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub agent_id: String,
+    pub hostname: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    pub created_at: chrono::DateTime<Utc>,
+    /// How far back the logs go, in seconds
+    pub window_secs: u64,
+    /// Paths of the bundled files inside the archive
+    pub files: Vec<String>,
+}
+
+impl BundleManifest {
+    pub fn new(config: &ClientConfig, window: Duration) -> Self {
+        Self {
+            agent_id: config.agent_id.clone(),
+            hostname: config.hostname.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            created_at: Utc::now(),
+            window_secs: window.as_secs(),
+            files: Vec::new(),
+        }
+    }
+}
+
+/// Parse a window such as `24h`, `90m`, `7d` or `3600` (seconds)
+pub fn parse_window(window: &str) -> Result<Duration> {
+    let window = window.trim();
+    let (number, unit) = window.split_at(window.find(|c: char| !c.is_ascii_digit()).unwrap_or(window.len()));
+    let number: u64 = number.parse().with_context(|| format!("Invalid time window {:?}", window))?;
+    let secs = match unit {
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        "d" => number * 24 * 60 * 60,
+        other => bail!("Unknown time unit {:?} in {:?}; use s, m, h or d", other, window),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// Log files in `dir` written to since `now - window`, oldest first, and
+/// the newest crash report if `include_crash` is set
+pub fn collect(dir: &Path, window: Duration, include_crash: bool, now: SystemTime) -> io::Result<Vec<PathBuf>> {
+    let since = now.checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+    let mut logs = Vec::new();
+    let mut crashes = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(CRASH_PREFIX) {
+            crashes.push(entry.path());
+        } else if name == LOG_FILE || name.starts_with(&format!("{}.", LOG_FILE)) {
+            let modified = entry.metadata()?.modified()?;
+            if modified >= since {
+                logs.push((modified, entry.path()));
+            }
+        }
+    }
+    logs.sort();
+
+    let mut files: Vec<PathBuf> = logs.into_iter().map(|(_, path)| path).collect();
+    if include_crash {
+        // Crash report names sort by time
+        files.extend(crashes.into_iter().max());
+    }
+    Ok(files)
+}
+
+/// Zip `files` into `out`, logs under `logs/` and crash reports under
+/// `crash/`, with the manifest listing them
+pub fn write_bundle<W: Write + Seek>(out: W, files: &[PathBuf], mut manifest: BundleManifest) -> Result<W> {
+    let mut zip = ZipWriter::new(out);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for path in files {
+        let name = path.file_name().context("Bundled path has no file name")?.to_string_lossy();
+        let entry = if name.starts_with(CRASH_PREFIX) {
+            format!("crash/{}", name)
+        } else {
+            format!("logs/{}", name)
+        };
+        zip.start_file(entry.as_str(), options)?;
+        let mut file = File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        io::copy(&mut file, &mut zip)?;
+        manifest.files.push(entry);
+    }
+    zip.start_file("manifest.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(zip.finish()?)
+}
+
+/// Upload endpoint for a device's bundles on the relay's host
+pub fn diagnostics_url(server_url: &str, agent_id: &str) -> Result<Url> {
+    let mut url = Url::parse(server_url).context("Invalid server URL")?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        other => bail!("Unsupported server URL scheme: {}", other),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow::anyhow!("Cannot convert {} to an HTTP URL", server_url))?;
+    url.set_path(&format!("/api/devices/{}/diagnostics", agent_id));
+    url.set_query(None);
+    Ok(url)
+}
+
+/// Sign and upload a bundle; returns the server's record of it
+pub async fn upload(config: &ClientConfig, bundle: Vec<u8>) -> Result<serde_json::Value> {
+    let Some(credential) = &config.credential else {
+        bail!("Device is not enrolled; uploads are signed with the enrollment key (use --output to save the bundle instead)");
+    };
+    let timestamp = Utc::now().timestamp();
+    let sha256 = hex::encode(digest(&SHA256, &bundle));
+    let signature = credential.sign_diagnostics(&config.agent_id, timestamp, &sha256)?;
+    let url = diagnostics_url(&config.server_url, &config.agent_id)?;
+
+    let response = crate::connection::proxy::http_client()
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/zip")
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, signature)
+        .body(bundle)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", url))?;
+
+    let status = response.status();
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    if !status.is_success() {
+        let reason = body.get("error").and_then(|e| e.as_str()).unwrap_or("no details");
+        bail!("Upload rejected ({}): {}", status, reason);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::enrollment::{diagnostics_payload, DeviceCredential};
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use ring::signature::{UnparsedPublicKey, ED25519};
+    use std::io::{Cursor, Read};
+    use tempfile::TempDir;
+    use wiremock::matchers::{header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn config(server_url: &str) -> ClientConfig {
+        ClientConfig::new(server_url.to_string(), Some("desk-01".to_string())).unwrap()
+    }
+
+    fn write_aged(dir: &Path, name: &str, contents: &str, age: Duration) {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("24h").unwrap(), Duration::from_secs(24 * 3600));
+        assert_eq!(parse_window("90m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_window("7d").unwrap(), Duration::from_secs(7 * 24 * 3600));
+        assert_eq!(parse_window("3600").unwrap(), Duration::from_secs(3600));
+        assert!(parse_window("1w").is_err());
+        assert!(parse_window("h").is_err());
+    }
+
+    #[test]
+    fn test_bundle_holds_recent_logs_crash_report_and_manifest() {
+        let dir = TempDir::new().unwrap();
+        let hour = Duration::from_secs(3600);
+        write_aged(dir.path(), LOG_FILE, "{\"message\":\"now\"}\n", Duration::ZERO);
+        write_aged(dir.path(), &format!("{}.1", LOG_FILE), "{\"message\":\"earlier\"}\n", 2 * hour);
+        write_aged(dir.path(), &format!("{}.2", LOG_FILE), "{\"message\":\"last week\"}\n", 7 * 24 * hour);
+        write_aged(dir.path(), "crash-20260101T000000Z.log", "old panic", 30 * 24 * hour);
+        write_aged(dir.path(), "crash-20260301T000000Z.log", "new panic", 10 * 24 * hour);
+        write_aged(dir.path(), "unrelated.txt", "not a log", Duration::ZERO);
+
+        let files = collect(dir.path(), 24 * hour, false, SystemTime::now()).unwrap();
+        let names: Vec<_> = files.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, [format!("{}.1", LOG_FILE), LOG_FILE.to_string()]);
+
+        let files = collect(dir.path(), 24 * hour, true, SystemTime::now()).unwrap();
+        let manifest = BundleManifest::new(&config("wss://relay.example.com/relay/ws"), 24 * hour);
+        let bundle = write_bundle(Cursor::new(Vec::new()), &files, manifest.clone()).unwrap().into_inner();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bundle)).unwrap();
+        let mut entries: Vec<_> = archive.file_names().map(str::to_string).collect();
+        entries.sort();
+        assert_eq!(entries, [
+            "crash/crash-20260301T000000Z.log".to_string(),
+            format!("logs/{}", LOG_FILE),
+            format!("logs/{}.1", LOG_FILE),
+            "manifest.json".to_string(),
+        ]);
+        let mut contents = String::new();
+        archive.by_name("crash/crash-20260301T000000Z.log").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "new panic");
+
+        let bundled: BundleManifest = serde_json::from_reader(archive.by_name("manifest.json").unwrap()).unwrap();
+        assert_eq!(bundled.agent_id, manifest.agent_id);
+        assert_eq!(bundled.window_secs, 24 * 3600);
+        assert_eq!(bundled.files.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_upload_is_signed_with_the_device_key() {
+        let server = MockServer::start().await;
+        let mut config = config(&server.uri().replace("http", "ws"));
+        Mock::given(method("POST"))
+            .and(path(format!("/api/devices/{}/diagnostics", config.agent_id)))
+            .and(header_exists(TIMESTAMP_HEADER))
+            .and(header_exists(SIGNATURE_HEADER))
+            .respond_with(ResponseTemplate::new(201).set_body_json(serde_json::json!({ "name": "bundle.zip" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        assert!(upload(&config, b"PK\x03\x04".to_vec()).await.unwrap_err().to_string().contains("not enrolled"));
+
+        let credential = DeviceCredential::generate().unwrap();
+        config.credential = Some(credential.clone());
+        let stored = upload(&config, b"PK\x03\x04".to_vec()).await.unwrap();
+        assert_eq!(stored["name"], "bundle.zip");
+
+        let request = &server.received_requests().await.unwrap()[0];
+        let header = |name: &str| request.headers.get(name).unwrap().to_str().unwrap().to_string();
+        let payload = diagnostics_payload(&config.agent_id, header(TIMESTAMP_HEADER).parse().unwrap(), &hex::encode(digest(&SHA256, b"PK\x03\x04")));
+        let public_key = BASE64.decode(credential.public_key_base64().unwrap()).unwrap();
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(payload.as_bytes(), &BASE64.decode(header(SIGNATURE_HEADER)).unwrap())
+            .unwrap();
+    }
+}
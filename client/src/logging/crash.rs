@@ -0,0 +1,800 @@
+//! Crash reports
+//!
+//! [`install`] hooks panics, and on Unix fatal signals (on Windows unhandled
+//! exceptions), such as a segfault in encoder FFI code. A panic writes a
+//! `crash-<time>-<id>.json` report next to the logs right away: version,
+//! OS, backtrace, the last log lines, the sessions that were running and
+//! the config with secrets redacted, never frame data. A signal handler can
+//! do next to nothing safely, so it only leaves a marker with the signal
+//! and time, which the next start turns into a report from the log and
+//! the last recorded context.
+//!
+//! Everything on the crash path is synchronous file I/O; the async runtime
+//! may be what broke. The context is kept behind a lock the hook only
+//! tries, so a panic while it is held cannot deadlock.
+//!
+//! Reports go to the server through [`upload_pending`], if the device's
+//! policy allows it or, without one, `crash_reports.upload` does. Sent
+//! reports are renamed `*.sent.json` and kept for diagnostics bundles.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError, TryLockError};
+use std::time::Duration;
+use tracing::warn;
+use url::Url;
+use uuid::Uuid;
+
+use super::bundle::{SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use super::{redact, tail, CRASH_PREFIX, LOG_FILE};
+use crate::config::ClientConfig;
+
+/// How often unsent reports are retried while the agent runs
+pub const UPLOAD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Reports kept in the log directory, sent or not
+const KEEP_REPORTS: usize = 20;
+
+/// Largest report the server accepts
+const MAX_UPLOAD_SIZE: usize = 1024 * 1024;
+
+/// Longest panic message and log line kept in a report
+const MAX_TEXT_LENGTH: usize = 4096;
+
+const REPORT_SUFFIX: &str = ".json";
+const SENT_SUFFIX: &str = ".sent.json";
+
+/// Left by the signal handler for the next start to turn into a report
+const SIGNAL_MARKER: &str = "fatal-signal";
+
+/// Present while the server has not heard of a crash through a heartbeat
+const CRASHED_MARKER: &str = "crashed";
+
+/// What the agent was doing, as last recorded, for signal reports
+const CONTEXT_FILE: &str = "running.json";
+
+/// Frames of our own code; the top one groups reports on the server
+const CRATE_PREFIXES: &[&str] = &["ghostlink_client::", "<ghostlink_client::"];
+
+/// Frames of the panic machinery, skipped when no frame is our own
+const RUNTIME_PREFIXES: &[&str] = &[
+    "std::", "core::", "alloc::", "<std::", "<core::", "<alloc::",
+    "backtrace::", "rust_begin_unwind", "__rust", "__rustc",
+];
+
+/// Crash reports and whether they reach the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrashReportPolicy {
+    /// Upload crash reports to the server; the device's policy overrides
+    /// this either way
+    pub upload: bool,
+    /// Lines of the log kept in a report
+    pub log_lines: usize,
+}
+
+impl Default for CrashReportPolicy {
+    fn default() -> Self {
+        Self { upload: false, log_lines: 200 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrashKind {
+    Panic,
+    /// A fatal signal, or an unhandled exception on Windows
+    Signal,
+}
+
+/// A crash report as written to disk and uploaded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrashReport {
+    pub id: Uuid,
+    /// Empty if the agent crashed before loading its config
+    pub agent_id: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    pub kind: CrashKind,
+    pub message: String,
+    /// Source location of a panic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    /// Topmost frame of our own code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_frame: Option<String>,
+    /// Signal number, or exception code on Windows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i64>,
+    #[serde(default)]
+    pub backtrace: Vec<String>,
+    /// Sessions running at the time
+    #[serde(default)]
+    pub sessions: Vec<String>,
+    #[serde(default)]
+    pub log_tail: Vec<String>,
+    /// The agent's config, redacted
+    #[serde(default)]
+    pub config: serde_json::Value,
+    pub crashed_at: DateTime<Utc>,
+}
+
+impl CrashReport {
+    fn new(kind: CrashKind, message: &str, crashed_at: DateTime<Utc>, context: &CrashContext, dir: &Path) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            agent_id: context.agent_id.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            kind,
+            message: truncate(&redact::redact(message), MAX_TEXT_LENGTH),
+            location: None,
+            thread: None,
+            top_frame: None,
+            signal: None,
+            backtrace: Vec::new(),
+            sessions: context.sessions.clone(),
+            log_tail: log_tail(dir, context.log_lines),
+            config: context.config.clone(),
+            crashed_at,
+        }
+    }
+}
+
+/// What a report records besides the crash itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct CrashContext {
+    agent_id: String,
+    sessions: Vec<String>,
+    config: serde_json::Value,
+    log_lines: usize,
+}
+
+impl Default for CrashContext {
+    fn default() -> Self {
+        Self {
+            agent_id: String::new(),
+            sessions: Vec::new(),
+            config: serde_json::Value::Null,
+            log_lines: CrashReportPolicy::default().log_lines,
+        }
+    }
+}
+
+/// Log directory reports go to, once [`install`]ed
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+static CONTEXT: Mutex<Option<CrashContext>> = Mutex::new(None);
+
+/// A panic since the last heartbeat; crashes that ended the process are
+/// marked on disk instead
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+/// Write crash reports to `dir` from now on, and turn a signal an earlier
+/// run died of into one
+pub fn install(dir: PathBuf) {
+    if DIR.set(dir.clone()).is_err() {
+        return;
+    }
+    match finalize_signal_report(&dir) {
+        Ok(Some(path)) => eprintln!("The agent crashed last time; wrote {}", path.display()),
+        Ok(None) => {}
+        Err(e) => eprintln!("Failed to write a report of the last crash: {:#}", e),
+    }
+    let _ = prune(&dir);
+
+    #[cfg(unix)]
+    unix::install(&dir);
+    #[cfg(windows)]
+    windows::install(&dir);
+
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        CRASHED.store(true, Ordering::SeqCst);
+        let message = info.payload().downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<dyn Any>".to_string());
+        let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+        let mut report = panic_report(&message, &backtrace, Utc::now(), &context(), &dir);
+        report.location = info.location().map(|location| location.to_string());
+        report.thread = std::thread::current().name().map(str::to_string);
+        let _ = write_report(&dir, &report);
+        let _ = File::create(dir.join(CRASHED_MARKER));
+        previous(info);
+    }));
+}
+
+/// Record the agent's ID and redacted config for reports
+pub fn set_config(config: &ClientConfig) {
+    let mut snapshot = serde_json::to_value(config).unwrap_or_default();
+    redact::redact_value(&mut snapshot);
+    update_context(|context| {
+        context.agent_id = config.agent_id.clone();
+        context.config = snapshot;
+        context.log_lines = config.crash_reports.log_lines;
+    });
+}
+
+/// Record the sessions running now for reports
+pub fn set_active_sessions(sessions: Vec<String>) {
+    update_context(|context| context.sessions = sessions);
+}
+
+fn update_context(change: impl FnOnce(&mut CrashContext)) {
+    let mut guard = CONTEXT.lock().unwrap_or_else(PoisonError::into_inner);
+    let context = guard.get_or_insert_with(CrashContext::default);
+    change(context);
+    // A signal handler cannot read memory it does not own, so the next
+    // start reads the context from here
+    if let (Some(dir), Ok(json)) = (DIR.get(), serde_json::to_vec(context)) {
+        let _ = fs::write(dir.join(CONTEXT_FILE), json);
+    }
+}
+
+/// The context, unless the panicking thread holds its lock
+fn context() -> CrashContext {
+    match CONTEXT.try_lock() {
+        Ok(guard) => guard.clone().unwrap_or_default(),
+        Err(TryLockError::Poisoned(e)) => e.into_inner().clone().unwrap_or_default(),
+        Err(TryLockError::WouldBlock) => CrashContext::default(),
+    }
+}
+
+/// The agent crashed since [`clear_crashed`] was last called, in this run
+/// or the one before
+pub fn crashed() -> bool {
+    CRASHED.load(Ordering::SeqCst) || DIR.get().is_some_and(|dir| dir.join(CRASHED_MARKER).exists())
+}
+
+/// The server heard of the crash
+pub fn clear_crashed() {
+    CRASHED.store(false, Ordering::SeqCst);
+    if let Some(dir) = DIR.get() {
+        let _ = fs::remove_file(dir.join(CRASHED_MARKER));
+    }
+}
+
+fn panic_report(message: &str, backtrace: &str, crashed_at: DateTime<Utc>, context: &CrashContext, dir: &Path) -> CrashReport {
+    let mut report = CrashReport::new(CrashKind::Panic, message, crashed_at, context, dir);
+    report.backtrace = frames(backtrace);
+    report.top_frame = top_frame(&report.backtrace);
+    report
+}
+
+/// Frames of a captured backtrace, each `symbol at file:line` where known
+fn frames(backtrace: &str) -> Vec<String> {
+    let mut frames: Vec<String> = Vec::new();
+    for line in backtrace.lines().map(str::trim) {
+        if let Some(at) = line.strip_prefix("at ") {
+            if let Some(frame) = frames.last_mut() {
+                frame.push_str(" at ");
+                frame.push_str(at);
+            }
+        } else if let Some((index, symbol)) = line.split_once(": ") {
+            if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) {
+                frames.push(symbol.trim().to_string());
+            }
+        }
+    }
+    frames
+}
+
+/// Topmost frame of our own code outside this module, else the topmost
+/// frame outside the panic machinery; without the symbol hash, which
+/// differs between builds
+fn top_frame(frames: &[String]) -> Option<String> {
+    let symbols = || frames.iter().map(|frame| symbol(frame));
+    symbols()
+        .find(|symbol| CRATE_PREFIXES.iter().any(|p| symbol.starts_with(p)) && !symbol.contains("::logging::crash::"))
+        .or_else(|| symbols().find(|symbol| !RUNTIME_PREFIXES.iter().any(|p| symbol.starts_with(p))))
+        .map(str::to_string)
+}
+
+fn symbol(frame: &str) -> &str {
+    let name = frame.split(" at ").next().unwrap_or(frame);
+    match name.rsplit_once("::h") {
+        Some((head, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => head,
+        _ => name,
+    }
+}
+
+/// The last `lines` lines of the log, redacted again in case an older
+/// build wrote them
+fn log_tail(dir: &Path, lines: usize) -> Vec<String> {
+    let Ok(file) = File::open(dir.join(LOG_FILE)) else {
+        return Vec::new();
+    };
+    tail::last_lines(file, lines)
+        .unwrap_or_default()
+        .iter()
+        .map(|line| truncate(&redact::redact(line), MAX_TEXT_LENGTH))
+        .collect()
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => text[..end].to_string(),
+        None => text.to_string(),
+    }
+}
+
+/// Write `report` under a name sorting by crash time
+fn write_report(dir: &Path, report: &CrashReport) -> io::Result<PathBuf> {
+    let id = report.id.simple().to_string();
+    let path = dir.join(format!(
+        "{}{}-{}{}",
+        CRASH_PREFIX,
+        report.crashed_at.format("%Y%m%dT%H%M%SZ"),
+        &id[..8],
+        REPORT_SUFFIX,
+    ));
+    fs::write(&path, serde_json::to_vec_pretty(report)?)?;
+    Ok(path)
+}
+
+/// Turn the marker the signal handler left into a report
+fn finalize_signal_report(dir: &Path) -> Result<Option<PathBuf>> {
+    let marker = dir.join(SIGNAL_MARKER);
+    let Ok(text) = fs::read_to_string(&marker) else {
+        return Ok(None);
+    };
+    let mut fields = text.split_whitespace().map(|field| field.parse::<i64>().ok());
+    let signal = fields.next().flatten();
+    let crashed_at = fields.next().flatten()
+        .and_then(|time| DateTime::from_timestamp(time, 0))
+        .unwrap_or_else(Utc::now);
+    let context: CrashContext = fs::read(dir.join(CONTEXT_FILE)).ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default();
+
+    let message = signal.map(describe_signal).unwrap_or_else(|| "Fatal signal".to_string());
+    let mut report = CrashReport::new(CrashKind::Signal, &message, crashed_at, &context, dir);
+    report.signal = signal;
+    let path = write_report(dir, &report).context("Failed to write the crash report")?;
+    File::create(dir.join(CRASHED_MARKER))?;
+    fs::remove_file(&marker)?;
+    Ok(Some(path))
+}
+
+#[cfg(unix)]
+use unix::describe as describe_signal;
+#[cfg(windows)]
+use windows::describe as describe_signal;
+
+/// Reports in `dir` not uploaded yet, oldest first
+pub fn pending(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut reports: Vec<PathBuf> = report_files(dir)?
+        .into_iter()
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.ends_with(REPORT_SUFFIX) && !name.ends_with(SENT_SUFFIX)
+        })
+        .collect();
+    reports.sort();
+    Ok(reports)
+}
+
+fn report_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(CRASH_PREFIX) {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Drop the oldest reports beyond [`KEEP_REPORTS`]
+fn prune(dir: &Path) -> io::Result<()> {
+    let mut files = report_files(dir)?;
+    files.sort();
+    let excess = files.len().saturating_sub(KEEP_REPORTS);
+    for path in &files[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Hand the reports in `dir` not uploaded yet to `send`, oldest first,
+/// marking each one sent as it goes. The first failure ends the round and
+/// leaves the rest for the next. Returns how many were sent.
+pub async fn upload_pending<F, Fut>(dir: &Path, agent_id: &str, mut send: F) -> Result<usize>
+where
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut sent = 0;
+    for path in pending(dir)? {
+        let body = match upload_body(&path, agent_id) {
+            Ok(body) => body,
+            Err(e) => {
+                // Retrying would fail the same way and hold up the rest
+                warn!("Setting aside unreadable crash report {}: {:#}", path.display(), e);
+                fs::rename(&path, path.with_extension("invalid"))?;
+                continue;
+            }
+        };
+        send(body).await.with_context(|| format!("Failed to upload {}", path.display()))?;
+        mark_sent(&path)?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// The report at `path` as uploaded: under this agent's ID if it crashed
+/// before knowing it, and without its log tail if too large
+fn upload_body(path: &Path, agent_id: &str) -> Result<Vec<u8>> {
+    let mut report: CrashReport = serde_json::from_slice(&fs::read(path)?)?;
+    if report.agent_id.is_empty() {
+        report.agent_id = agent_id.to_string();
+    }
+    let mut body = serde_json::to_vec(&report)?;
+    if body.len() > MAX_UPLOAD_SIZE {
+        report.log_tail.clear();
+        report.backtrace.truncate(100);
+        body = serde_json::to_vec(&report)?;
+    }
+    Ok(body)
+}
+
+fn mark_sent(path: &Path) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let sent = format!("{}{}", name.trim_end_matches(REPORT_SUFFIX), SENT_SUFFIX);
+    fs::rename(path, path.with_file_name(sent))
+}
+
+/// Upload endpoint for crash reports on the relay's host
+pub fn upload_url(server_url: &str) -> Result<Url> {
+    let mut url = Url::parse(server_url).context("Invalid server URL")?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        other => bail!("Unsupported server URL scheme: {}", other),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow::anyhow!("Cannot convert {} to an HTTP URL", server_url))?;
+    url.set_path("/api/crash-reports");
+    url.set_query(None);
+    Ok(url)
+}
+
+/// Sign and upload one report
+pub async fn upload(config: &ClientConfig, client: &reqwest::Client, body: Vec<u8>) -> Result<()> {
+    let Some(credential) = &config.credential else {
+        bail!("Device is not enrolled; uploads are signed with the enrollment key");
+    };
+    let timestamp = Utc::now().timestamp();
+    let sha256 = hex::encode(digest(&SHA256, &body));
+    let signature = credential.sign_crash_report(&config.agent_id, timestamp, &sha256)?;
+    let url = upload_url(&config.server_url)?;
+
+    let response = client
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, signature)
+        .body(body)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", url))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body: serde_json::Value = response.json().await.unwrap_or_default();
+        let reason = body.get("error").and_then(|e| e.as_str()).unwrap_or("no details");
+        bail!("Upload rejected ({}): {}", status, reason);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::sync::OnceLock;
+
+    const SIGNALS: [libc::c_int; 5] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGILL, libc::SIGFPE, libc::SIGABRT];
+
+    static MARKER: OnceLock<CString> = OnceLock::new();
+    static PREVIOUS: OnceLock<[libc::sigaction; 5]> = OnceLock::new();
+
+    pub fn install(dir: &Path) {
+        let Ok(marker) = CString::new(dir.join(super::SIGNAL_MARKER).as_os_str().as_bytes()) else {
+            return;
+        };
+        if MARKER.set(marker).is_err() {
+            return;
+        }
+        // SAFETY: the handler only makes async-signal-safe calls
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_fatal_signal as *const () as usize;
+            // On the alternate stack Rust sets up, so stack overflows are caught too
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: [libc::sigaction; 5] = std::mem::zeroed();
+            for (signal, previous) in SIGNALS.iter().zip(previous.iter_mut()) {
+                libc::sigaction(*signal, &action, previous);
+            }
+            let _ = PREVIOUS.set(previous);
+        }
+    }
+
+    /// Leave `<signal> <unix time>` in the marker file, then hand the
+    /// signal to the handler before ours: Rust's reports stack overflows,
+    /// the default one ends the process
+    extern "C" fn on_fatal_signal(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+        // No allocation and no locks from here on
+        if let Some(marker) = MARKER.get() {
+            let mut line = [0u8; 48];
+            let mut len = write_number(&mut line, signal as u64);
+            line[len] = b' ';
+            len += 1;
+            // SAFETY: time(2) is async-signal-safe
+            len += write_number(&mut line[len..], unsafe { libc::time(std::ptr::null_mut()) } as u64);
+            line[len] = b'\n';
+            len += 1;
+            // SAFETY: open, write and close are async-signal-safe
+            unsafe {
+                let fd = libc::open(marker.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, 0o600 as libc::c_uint);
+                if fd >= 0 {
+                    libc::write(fd, line.as_ptr().cast(), len);
+                    libc::close(fd);
+                }
+            }
+        }
+
+        // SAFETY: restoring a disposition saved by `install`
+        unsafe {
+            match (PREVIOUS.get(), SIGNALS.iter().position(|&s| s == signal)) {
+                (Some(previous), Some(index)) => libc::sigaction(signal, &previous[index], std::ptr::null_mut()),
+                _ => libc::sigaction(signal, &std::mem::zeroed::<libc::sigaction>(), std::ptr::null_mut()),
+            };
+            // A fault recurs on return; a signal sent by kill(2) does not
+            if info.is_null() || (*info).si_code <= 0 {
+                libc::raise(signal);
+            }
+        }
+    }
+
+    fn write_number(out: &mut [u8], mut n: u64) -> usize {
+        let mut digits = [0u8; 20];
+        let mut count = 0;
+        loop {
+            digits[count] = b'0' + (n % 10) as u8;
+            count += 1;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        for (i, digit) in digits[..count].iter().rev().enumerate() {
+            out[i] = *digit;
+        }
+        count
+    }
+
+    pub fn describe(signal: i64) -> String {
+        let name = match signal as libc::c_int {
+            libc::SIGSEGV => "Segmentation fault (SIGSEGV)",
+            libc::SIGBUS => "Bus error (SIGBUS)",
+            libc::SIGILL => "Illegal instruction (SIGILL)",
+            libc::SIGFPE => "Arithmetic exception (SIGFPE)",
+            libc::SIGABRT => "Aborted (SIGABRT)",
+            _ => return format!("Fatal signal {}", signal),
+        };
+        name.to_string()
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::path::{Path, PathBuf};
+    use std::sync::OnceLock;
+    use windows::Win32::System::Diagnostics::Debug::{
+        SetUnhandledExceptionFilter, EXCEPTION_POINTERS, LPTOP_LEVEL_EXCEPTION_FILTER,
+    };
+
+    /// Returned by an exception filter to let the next one handle it
+    const EXCEPTION_CONTINUE_SEARCH: i32 = 0;
+
+    static MARKER: OnceLock<PathBuf> = OnceLock::new();
+    static PREVIOUS: OnceLock<LPTOP_LEVEL_EXCEPTION_FILTER> = OnceLock::new();
+
+    pub fn install(dir: &Path) {
+        if MARKER.set(dir.join(super::SIGNAL_MARKER)).is_err() {
+            return;
+        }
+        // SAFETY: replacing the process-wide filter, which we chain to
+        let previous = unsafe { SetUnhandledExceptionFilter(Some(on_unhandled_exception)) };
+        let _ = PREVIOUS.set(previous);
+    }
+
+    /// Leave `<exception code> <unix time>` in the marker file, then let the
+    /// filter before ours, or Windows Error Reporting, have the exception
+    unsafe extern "system" fn on_unhandled_exception(info: *const EXCEPTION_POINTERS) -> i32 {
+        let code = info.as_ref()
+            .and_then(|info| info.ExceptionRecord.as_ref())
+            .map(|record| record.ExceptionCode.0 as u32)
+            .unwrap_or(0);
+        // Best effort: the heap may be what broke
+        if let Some(marker) = MARKER.get() {
+            let _ = std::fs::write(marker, format!("{} {}\n", code, chrono::Utc::now().timestamp()));
+        }
+        match PREVIOUS.get().copied().flatten() {
+            Some(previous) => previous(info),
+            None => EXCEPTION_CONTINUE_SEARCH,
+        }
+    }
+
+    pub fn describe(code: i64) -> String {
+        let name = match code as u32 {
+            0xC000_0005 => "Access violation (0xC0000005)",
+            0xC000_00FD => "Stack overflow (0xC00000FD)",
+            0xC000_001D => "Illegal instruction (0xC000001D)",
+            0xC000_0094 => "Integer division by zero (0xC0000094)",
+            0xC000_0409 => "Stack buffer overrun (0xC0000409)",
+            _ => return format!("Unhandled exception 0x{:08X}", code as u32),
+        };
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const BACKTRACE: &str = "   0: std::backtrace::Backtrace::force_capture
+             at /rustc/abc/library/std/src/backtrace.rs:312:13
+   1: ghostlink_client::logging::crash::install::{{closure}}
+   2: std::panicking::rust_panic_with_hook
+   3: core::panicking::panic_fmt
+   4: ghostlink_client::encoding::software::SoftwareEncoder::encode::h0123456789abcdef
+             at ./src/encoding/software.rs:88:9
+   5: ghostlink_client::agent::GhostLinkAgent::start::{{closure}}
+";
+
+    fn sample_context() -> CrashContext {
+        let mut config = serde_json::json!({
+            "agent_id": "4f6c2d1e-0000-4000-8000-000000000001",
+            "server_url": "wss://relay.example.com/relay/ws",
+            "support_code": "K7Q2-9XRT",
+            "credential": { "private_key": "MC4CAQAwBQYDK2VwBCIEI", "agent_id": "x" },
+            "proxy": { "password": "hunter2", "host": "proxy.example.com" },
+            "encoding": { "codec": "h264" },
+        });
+        redact::redact_value(&mut config);
+        CrashContext {
+            agent_id: "4f6c2d1e-0000-4000-8000-000000000001".to_string(),
+            sessions: vec!["session-1".to_string()],
+            config,
+            log_lines: 2,
+        }
+    }
+
+    #[test]
+    fn test_panic_report_round_trip() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(LOG_FILE), "one\ntwo\nconnecting with enroll_token=abc123\n").unwrap();
+        let crashed_at = DateTime::from_timestamp(1_760_000_000, 0).unwrap();
+
+        let report = panic_report("index out of bounds", BACKTRACE, crashed_at, &sample_context(), dir.path());
+        assert_eq!(report.kind, CrashKind::Panic);
+        assert_eq!(report.top_frame.as_deref(), Some("ghostlink_client::encoding::software::SoftwareEncoder::encode"));
+        assert_eq!(report.backtrace.len(), 6);
+        assert_eq!(report.backtrace[4], "ghostlink_client::encoding::software::SoftwareEncoder::encode::h0123456789abcdef at ./src/encoding/software.rs:88:9");
+        assert_eq!(report.sessions, vec!["session-1".to_string()]);
+        assert_eq!(report.log_tail, vec!["two".to_string(), "connecting with enroll_token=[REDACTED]".to_string()]);
+
+        let path = write_report(dir.path(), &report).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("crash-20251009T085320Z-") && name.ends_with(".json"), "{}", name);
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json["kind"], "panic");
+        assert_eq!(json["crashed_at"], "2025-10-09T08:53:20Z");
+        assert!(json.get("signal").is_none());
+        let decoded: CrashReport = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, report);
+    }
+
+    #[test]
+    fn test_report_config_is_redacted() {
+        let dir = TempDir::new().unwrap();
+        let report = panic_report("boom", "", Utc::now(), &sample_context(), dir.path());
+        let config = &report.config;
+        assert_eq!(config["support_code"], "[REDACTED]");
+        assert_eq!(config["credential"], "[REDACTED]");
+        assert_eq!(config["proxy"]["password"], "[REDACTED]");
+        assert_eq!(config["proxy"]["host"], "proxy.example.com");
+        assert_eq!(config["encoding"]["codec"], "h264");
+        let text = serde_json::to_string(&report).unwrap();
+        for secret in ["K7Q2-9XRT", "MC4CAQAwBQYDK2VwBCIEI", "hunter2"] {
+            assert!(!text.contains(secret), "report leaks {}", secret);
+        }
+    }
+
+    #[test]
+    fn test_signal_marker_becomes_report() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(CONTEXT_FILE), serde_json::to_vec(&sample_context()).unwrap()).unwrap();
+        fs::write(dir.path().join(SIGNAL_MARKER), "11 1760000000\n").unwrap();
+
+        let path = finalize_signal_report(dir.path()).unwrap().unwrap();
+        let report: CrashReport = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        assert_eq!(report.kind, CrashKind::Signal);
+        assert_eq!(report.signal, Some(11));
+        assert_eq!(report.crashed_at, DateTime::from_timestamp(1_760_000_000, 0).unwrap());
+        assert_eq!(report.sessions, vec!["session-1".to_string()]);
+        assert!(!dir.path().join(SIGNAL_MARKER).exists());
+        assert!(dir.path().join(CRASHED_MARKER).exists());
+        assert!(finalize_signal_report(dir.path()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upload_loop_stops_at_first_failure() {
+        let dir = TempDir::new().unwrap();
+        let mut context = sample_context();
+        context.agent_id.clear();
+        for (i, message) in ["first", "second", "third"].iter().enumerate() {
+            let crashed_at = DateTime::from_timestamp(1_760_000_000 + i as i64, 0).unwrap();
+            write_report(dir.path(), &panic_report(message, "", crashed_at, &context, dir.path())).unwrap();
+        }
+        fs::write(dir.path().join("crash-20250101T000000Z-broken00.json"), "not json").unwrap();
+
+        let mut bodies = Vec::new();
+        let sent = upload_pending(dir.path(), "agent-1", |body| {
+            bodies.push(body);
+            let fail = bodies.len() == 2;
+            async move {
+                if fail {
+                    bail!("server unavailable");
+                }
+                Ok(())
+            }
+        })
+        .await;
+        assert!(sent.is_err());
+        assert_eq!(bodies.len(), 2);
+        let first: CrashReport = serde_json::from_slice(&bodies[0]).unwrap();
+        assert_eq!(first.message, "first");
+        assert_eq!(first.agent_id, "agent-1");
+        assert_eq!(pending(dir.path()).unwrap().len(), 2);
+        assert!(dir.path().join("crash-20250101T000000Z-broken00.invalid").exists());
+
+        let mut messages = Vec::new();
+        let sent = upload_pending(dir.path(), "agent-1", |body| {
+            messages.push(serde_json::from_slice::<CrashReport>(&body).unwrap().message);
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(sent, 2);
+        assert_eq!(messages, vec!["second".to_string(), "third".to_string()]);
+        assert!(pending(dir.path()).unwrap().is_empty());
+        let sent_files = report_files(dir.path()).unwrap().into_iter()
+            .filter(|path| path.to_string_lossy().ends_with(SENT_SUFFIX))
+            .count();
+        assert_eq!(sent_files, 3);
+    }
+
+    #[test]
+    fn test_upload_url() {
+        assert_eq!(upload_url("wss://relay.example.com/relay/ws").unwrap().as_str(), "https://relay.example.com/api/crash-reports");
+        assert_eq!(upload_url("ws://localhost:8443").unwrap().as_str(), "http://localhost:8443/api/crash-reports");
+    }
+}
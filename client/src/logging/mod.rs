@@ -0,0 +1,191 @@
+//! Log files
+//!
+//! The agent writes JSON lines to `ghostlink.log` in its log directory,
+//! rotating the file once it outgrows `max_file_bytes` and keeping
+//! `keep_files` rotations. Runs from a terminal log to the console as well.
+//! Both pass through [`redact::Redacting`], so credentials reach neither.
+//! Crashes leave a `crash-<time>-<id>.json` report next to the logs (see
+//! [`crash`]), which `logs upload --include-crash` adds to the diagnostics
+//! bundle.
+
+pub mod bundle;
+pub mod crash;
+pub mod redact;
+pub mod rotation;
+pub mod tail;
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use tracing::Subscriber;
+use tracing_subscriber::{fmt, registry::LookupSpan, reload, EnvFilter, Layer, Registry};
+
+use redact::Redacting;
+use rotation::RotatingFile;
+
+/// Current log file; rotations append `.1`, `.2`, ...
+pub const LOG_FILE: &str = "ghostlink.log";
+
+/// File name prefix of crash reports
+pub const CRASH_PREFIX: &str = "crash-";
+
+/// Environment variable holding the log level or filter directives
+pub const LEVEL_ENV: &str = "GHOSTLINK_LOG";
+
+/// Layer on the bare registry, as [`init`] takes for the console
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Swaps the filter [`init`] installed, for config reloads
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Where and how much the agent logs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Log directory; unset uses the platform's (see [`default_dir`])
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    /// Size at which the log file is rotated, in bytes
+    pub max_file_bytes: u64,
+    /// Rotated files kept besides the current one
+    pub keep_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_file_bytes: 10 * 1024 * 1024,
+            keep_files: 5,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn log_dir(&self) -> PathBuf {
+        self.dir.clone().unwrap_or_else(default_dir)
+    }
+}
+
+/// `%ProgramData%\GhostLink\logs` on Windows, `/Library/Logs/GhostLink`
+/// (or `~/Library/Logs/GhostLink`) on macOS, and `/var/log/ghostlink`
+/// for root elsewhere, otherwise the user's state directory
+pub fn default_dir() -> PathBuf {
+    #[cfg(windows)]
+    {
+        std::env::var_os("ProgramData")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"))
+            .join("GhostLink")
+            .join("logs")
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        if crate::elevation::sudo::is_root() {
+            return PathBuf::from("/Library/Logs/GhostLink");
+        }
+        dirs::home_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("Library/Logs/GhostLink")
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        if crate::elevation::sudo::is_root() {
+            return PathBuf::from("/var/log/ghostlink");
+        }
+        dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .unwrap_or_else(std::env::temp_dir)
+            .join("ghostlink")
+            .join("logs")
+    }
+}
+
+/// Filter directives from `--log-level`, else `GHOSTLINK_LOG`, else the
+/// config's `log_level`
+pub fn pick_directives(flag: Option<&str>, env: Option<&str>, configured: &str) -> String {
+    [flag, env]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .find(|directives| !directives.is_empty())
+        .unwrap_or(configured)
+        .to_string()
+}
+
+/// Filter for `--log-level` (or `GHOSTLINK_LOG`, or `configured`); a
+/// filter that does not parse falls back to `info`
+pub fn level_filter(flag: Option<&str>, configured: &str) -> EnvFilter {
+    let env = std::env::var(LEVEL_ENV).ok();
+    let directives = pick_directives(flag, env.as_deref(), configured);
+    EnvFilter::try_new(&directives).unwrap_or_else(|e| {
+        eprintln!("Ignoring log level {:?}: {}", directives, e);
+        EnvFilter::new("info")
+    })
+}
+
+/// Layer writing redacted JSON lines to the rotating log file
+pub fn file_layer<S>(config: &LoggingConfig) -> std::io::Result<impl Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let file = RotatingFile::open(&config.log_dir(), config.max_file_bytes, config.keep_files)?;
+    Ok(fmt::layer()
+        .json()
+        .with_current_span(false)
+        .with_writer(Redacting::new(Mutex::new(file))))
+}
+
+/// Layer for interactive runs, printing redacted lines to stderr so stdout
+/// is left to command output
+pub fn console_layer<S>() -> impl Layer<S> + Send + Sync
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fmt::layer().with_writer(Redacting::new(std::io::stderr))
+}
+
+/// Install the global subscriber: the log file, plus `console` if given.
+/// A log directory that cannot be written leaves only the console.
+pub fn init(config: &LoggingConfig, filter: EnvFilter, console: Option<BoxedLayer>) {
+    use tracing_subscriber::prelude::*;
+
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    match file_layer(config) {
+        Ok(layer) => {
+            crash::install(config.log_dir());
+            layers.push(Box::new(layer));
+        }
+        Err(e) => eprintln!("Not logging to {}: {}", config.log_dir().display(), e),
+    }
+    layers.extend(console);
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = FILTER.set(handle);
+    tracing_subscriber::registry()
+        .with(layers.with_filter(filter))
+        .init();
+}
+
+/// Replace the filter of the subscriber [`init`] installed
+pub fn set_filter(filter: EnvFilter) {
+    let Some(handle) = FILTER.get() else {
+        return;
+    };
+    if let Err(e) = handle.reload(filter) {
+        eprintln!("Failed to change the log level: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flag_beats_environment_beats_config() {
+        assert_eq!(pick_directives(Some("debug"), Some("trace"), "info"), "debug");
+        assert_eq!(pick_directives(None, Some("ghostlink_client::connection=trace"), "info"), "ghostlink_client::connection=trace");
+        assert_eq!(pick_directives(Some(" "), Some(""), "warn"), "warn");
+    }
+}
@@ -0,0 +1,143 @@
+//! Size-based rotation of the agent's log file
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::LOG_FILE;
+
+/// Path of the `n`th rotation of the log in `dir`; 1 is the newest
+pub fn rotated_path(dir: &Path, n: usize) -> PathBuf {
+    dir.join(format!("{}.{}", LOG_FILE, n))
+}
+
+/// `ghostlink.log`, moved to `ghostlink.log.1` (pushing older rotations
+/// up to `ghostlink.log.<keep>`) before a write would take it past
+/// `max_bytes`. Rotation happens between writes, and the subscriber
+/// writes an event at a time, so no line is split across files.
+pub struct RotatingFile {
+    dir: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<File>,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(dir: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            keep,
+            file: Some(file),
+            written,
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(LOG_FILE)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Windows cannot rename a file that is still open
+        self.file = None;
+
+        let current = self.path();
+        if self.keep == 0 {
+            remove_if_exists(&current)?;
+        } else {
+            remove_if_exists(&rotated_path(&self.dir, self.keep))?;
+            for n in (1..self.keep).rev() {
+                let from = rotated_path(&self.dir, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.dir, n + 1))?;
+                }
+            }
+            fs::rename(&current, rotated_path(&self.dir, 1))?;
+        }
+
+        self.file = Some(OpenOptions::new().create(true).write(true).truncate(true).open(&current)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            // A failed rotation left no file open; try again
+            None => self.file.insert(OpenOptions::new().create(true).append(true).open(self.path())?),
+        };
+        file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn line(n: usize) -> Vec<u8> {
+        format!("{{\"line\":{:04}}}\n", n).into_bytes()
+    }
+
+    #[test]
+    fn test_rotates_by_size_and_keeps_the_newest_files() {
+        let dir = TempDir::new().unwrap();
+        let line_len = line(0).len() as u64;
+        let mut file = RotatingFile::open(dir.path(), line_len * 3, 2).unwrap();
+        for n in 0..10 {
+            file.write_all(&line(n)).unwrap();
+        }
+        file.flush().unwrap();
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(file.path()), "{\"line\":0009}\n");
+        assert_eq!(read(rotated_path(dir.path(), 1)), "{\"line\":0006}\n{\"line\":0007}\n{\"line\":0008}\n");
+        assert_eq!(read(rotated_path(dir.path(), 2)), "{\"line\":0003}\n{\"line\":0004}\n{\"line\":0005}\n");
+        assert!(!rotated_path(dir.path(), 3).exists());
+    }
+
+    #[test]
+    fn test_reopening_appends_and_oversized_lines_still_land() {
+        let dir = TempDir::new().unwrap();
+        RotatingFile::open(dir.path(), 1024, 1).unwrap().write_all(b"before restart\n").unwrap();
+        let mut file = RotatingFile::open(dir.path(), 16, 1).unwrap();
+
+        // Already past the limit, so the first write rotates; a line longer
+        // than the limit gets a file to itself
+        file.write_all(b"a line longer than the limit\n").unwrap();
+        file.write_all(b"short\n").unwrap();
+        assert_eq!(fs::read_to_string(rotated_path(dir.path(), 1)).unwrap(), "a line longer than the limit\n");
+        assert_eq!(fs::read_to_string(file.path()).unwrap(), "short\n");
+
+        let mut unkept = RotatingFile::open(&dir.path().join("none"), 8, 0).unwrap();
+        unkept.write_all(b"12345678").unwrap();
+        unkept.write_all(b"9").unwrap();
+        assert_eq!(fs::read_to_string(unkept.path()).unwrap(), "9");
+        assert!(!rotated_path(&dir.path().join("none"), 1).exists());
+    }
+}
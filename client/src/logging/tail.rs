@@ -0,0 +1,116 @@
+//! `logs tail`: follow the agent's log file
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// How often the log is checked for new lines
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Print the last `lines` lines of `path`, then new lines as they are
+/// written, until interrupted. A rotation shows up as the file shrinking,
+/// and following picks up at the start of the new file.
+pub async fn follow(path: &Path, lines: usize, json: bool) -> Result<()> {
+    let print = |line: &str| {
+        if json {
+            println!("{}", line);
+        } else {
+            println!("{}", format_line(line));
+        }
+    };
+
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut position = file.metadata()?.len();
+    for line in last_lines(file, lines)? {
+        print(&line);
+    }
+
+    let mut partial = String::new();
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let Ok(mut file) = File::open(path) else { continue };
+        let len = file.metadata()?.len();
+        if len < position {
+            position = 0;
+            partial.clear();
+        }
+        if len == position {
+            continue;
+        }
+        file.seek(SeekFrom::Start(position))?;
+        let mut chunk = Vec::new();
+        file.take(len - position).read_to_end(&mut chunk)?;
+        position = len;
+
+        partial.push_str(&String::from_utf8_lossy(&chunk));
+        while let Some(end) = partial.find('\n') {
+            let line: String = partial.drain(..=end).collect();
+            print(line.trim_end());
+        }
+    }
+}
+
+pub(super) fn last_lines(file: File, count: usize) -> Result<Vec<String>> {
+    let mut lines = std::collections::VecDeque::with_capacity(count);
+    for line in BufReader::new(file).lines() {
+        if lines.len() == count {
+            lines.pop_front();
+        }
+        if count > 0 {
+            lines.push_back(line?);
+        }
+    }
+    Ok(lines.into())
+}
+
+/// A JSON log line as `time LEVEL target: message key=value ...`; other
+/// lines are returned unchanged
+pub fn format_line(line: &str) -> String {
+    let Ok(serde_json::Value::Object(event)) = serde_json::from_str::<serde_json::Value>(line) else {
+        return line.to_string();
+    };
+    let text = |key: &str| event.get(key).and_then(|value| value.as_str()).unwrap_or_default();
+
+    let mut formatted = format!("{} {:>5} {}:", text("timestamp"), text("level"), text("target"));
+    if let Some(serde_json::Value::Object(fields)) = event.get("fields") {
+        if let Some(message) = fields.get("message").and_then(|message| message.as_str()) {
+            formatted.push(' ');
+            formatted.push_str(message);
+        }
+        for (key, value) in fields.iter().filter(|(key, _)| *key != "message") {
+            match value {
+                serde_json::Value::String(value) => formatted.push_str(&format!(" {}={}", key, value)),
+                value => formatted.push_str(&format!(" {}={}", key, value)),
+            }
+        }
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_formats_json_events() {
+        let line = r#"{"timestamp":"2026-10-16T09:30:00.000000Z","level":"INFO","fields":{"message":"Connected","attempt":2,"server":"relay"},"target":"ghostlink_client::connection"}"#;
+        assert_eq!(
+            format_line(line),
+            "2026-10-16T09:30:00.000000Z  INFO ghostlink_client::connection: Connected attempt=2 server=relay"
+        );
+        assert_eq!(format_line("plain text"), "plain text");
+    }
+
+    #[test]
+    fn test_last_lines() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"one\ntwo\nthree\n").unwrap();
+        file.rewind().unwrap();
+        assert_eq!(last_lines(file.try_clone().unwrap(), 2).unwrap(), ["two", "three"]);
+        file.rewind().unwrap();
+        assert!(last_lines(file, 0).unwrap().is_empty());
+    }
+}
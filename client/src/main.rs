@@ -1,29 +1,60 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use tracing::{info, warn, error};
 
 mod error;
 mod agent;
 mod capture;
+mod chat;
+mod clipboard;
 mod config;
 mod connection;
+mod control;
+mod elevation;
+mod file_transfer;
 mod service;
 mod session;
 mod input;
+mod logging;
+mod network;
+mod output;
+mod permissions;
+mod policy;
+mod recording;
+mod registry;
+mod resources;
+mod terminal;
 
 mod toolbox;
+mod updater;
+#[cfg(feature = "viewer")]
+mod viewer;
 
 use error::Result;
+use output::OutputFormat;
+use std::process::ExitCode;
 
 use crate::{
     agent::Agent,
     config::ClientConfig,
-    service::ServiceManager,
+    connection::enrollment::EnrollWith,
+    connection::proxy::ProxySettings,
+    service::{InstallOptions, ServiceManager},
 };
 
 #[derive(Parser)]
 #[command(name = "atlasconnect-client")]
 #[command(about = "AtlasConnect Client Agent - ScreenConnect-like remote access client")]
 struct Cli {
+    /// Log level or filter directives (e.g. debug, ghostlink_client::connection=trace);
+    /// overrides GHOSTLINK_LOG and the config's log_level
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// Config file to use instead of the platform's; GHOSTLINK_CONFIG also
+    /// names one
+    #[arg(long, global = true)]
+    config: Option<std::path::PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -32,6 +63,40 @@ struct Cli {
 enum Commands {
     /// Start the client agent (connects to server)
     Start {
+        /// Server URL to connect to (defaults to the one in the client config)
+        #[arg(short, long)]
+        server: Option<String>,
+        
+        /// Device name override
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Enrollment token from the server; enrolls this device before connecting
+        #[arg(long)]
+        enroll_token: Option<String>,
+    },
+    
+    /// Enroll this device with an invitation code from an admin
+    Enroll {
+        /// Invitation code
+        #[arg(short, long)]
+        code: String,
+
+        /// Server URL to connect to (defaults to the one in the client config)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Device name override
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+
+    /// Join a technician's support session with the code they gave you
+    Join {
+        /// Support code from the technician
+        #[arg(short, long)]
+        code: String,
+        
         /// Server URL to connect to
         #[arg(short, long, default_value = "wss://relay.cktechx.com")]
         server: String,
@@ -39,6 +104,10 @@ enum Commands {
         /// Device name override
         #[arg(short, long)]
         name: Option<String>,
+        
+        /// Seconds without technician input before the session ends (0 never ends it)
+        #[arg(long)]
+        idle_timeout: Option<u64>,
     },
     
     /// Install as system service
@@ -46,16 +115,88 @@ enum Commands {
         /// Server URL to connect to
         #[arg(short, long, default_value = "wss://relay.cktechx.com")]
         server: String,
+
+        /// Enrollment token from the server; enrolls this device before installing
+        #[arg(long)]
+        enroll_token: Option<String>,
+
+        /// PEM file of a private CA to trust for the relay; copied next to the config
+        #[arg(long)]
+        ca_cert: Option<std::path::PathBuf>,
+
+        /// SHA-256 of the relay certificate's public key (hex or base64); may be repeated
+        #[arg(long = "pin")]
+        pins: Vec<String>,
+
+        /// Account to run the service as (defaults to LocalSystem / root)
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Password for --account (Windows only)
+        #[arg(long, requires = "account")]
+        password: Option<String>,
+
+        /// Start the service on demand instead of at boot
+        #[arg(long)]
+        manual_start: bool,
+
+        /// Make the OS read-only to the agent (systemd ProtectSystem=full)
+        #[arg(long)]
+        protect_system: bool,
+
+        /// Forbid privilege gains (systemd NoNewPrivileges); disables sudo/pkexec elevation
+        #[arg(long)]
+        no_new_privileges: bool,
+
+        /// Print the service definition instead of installing it
+        #[arg(long)]
+        dry_run: bool,
     },
     
     /// Uninstall system service
     Uninstall,
+
+    /// Run under the Windows service control manager (used by the installed service)
+    #[cfg(windows)]
+    #[command(hide = true)]
+    Service {
+        #[arg(short, long, default_value = "wss://relay.cktechx.com")]
+        server: String,
+    },
+
+    /// Capture and inject in this session for the service (started by the service)
+    #[cfg(windows)]
+    #[command(hide = true)]
+    SessionHelper {
+        #[arg(long)]
+        pipe: String,
+    },
     
     /// Show service status
-    Status,
+    Status {
+        /// text or json
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
     
     /// Generate device info
-    Info,
+    Info {
+        /// Print the saved config with the server's policy applied instead
+        #[arg(long, conflicts_with = "output")]
+        effective_config: bool,
+
+        /// text or json
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+    
+    /// Check the proxy, then capture, encoding, input and the relay the
+    /// way a session start does, and print what passed
+    Doctor {
+        /// Server URL to check the relay against (defaults to the one in the client config)
+        #[arg(short, long)]
+        server: Option<String>,
+    },
     
     /// Launch session window (called by web GUI)
     Session {
@@ -67,7 +208,7 @@ enum Commands {
         #[arg(short = 'u', long, default_value = "wss://relay.cktechx.com")]
         server_url: String,
         
-        /// Authentication token
+        /// Session token from the launch URL
         #[arg(short, long)]
         token: String,
     },
@@ -77,26 +218,160 @@ enum Commands {
         #[command(subcommand)]
         action: ToolboxAction,
     },
+    
+    /// Chat with the technician from the console
+    Chat {
+        #[command(subcommand)]
+        action: ChatAction,
+    },
+    
+    /// Show the end user the agent's connection and sessions, and let them
+    /// end a session, pause remote access or join a support code; opens a
+    /// window without an action
+    Tray {
+        #[command(subcommand)]
+        action: Option<TrayAction>,
+    },
+    
+    /// List and export session recordings
+    Recording {
+        /// Recording directory (defaults to the one in the client config)
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+        
+        #[command(subcommand)]
+        action: RecordingAction,
+    },
+    
+    /// Upload or follow the agent's logs
+    Logs {
+        /// Log directory (defaults to the one in the client config)
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+        
+        #[command(subcommand)]
+        action: LogsAction,
+    },
+    
+    /// Check or print the client config
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    
+    /// Print a shell completion script
+    Completions {
+        /// bash, zsh, fish, elvish or powershell
+        shell: clap_complete::Shell,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
-
+async fn main() -> ExitCode {
     let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+/// Run the requested command; its error decides the exit status
+async fn run(cli: Cli) -> Result<()> {
+    if let Some(path) = cli.config.clone() {
+        ClientConfig::use_path(path);
+    }
+    // Checking the config must not trip over it while setting up logging
+    if let Commands::Config { action } = cli.command {
+        return handle_config_action(action);
+    }
+
+    // Initialize logging to the log file, and to the console when run from
+    // a terminal; the Windows service has no console, so it logs to the
+    // event log instead
+    let saved = saved_config();
+    let configured_level = saved.as_ref().map_or("info", |config| config.log_level.as_str());
+    let filter = logging::level_filter(cli.log_level.as_deref(), configured_level);
+    let logging_config = saved.as_ref().map(|config| config.logging.clone()).unwrap_or_default();
+    // Capturers and HTTP clients are also created where no config is at hand
+    saved.as_ref().map(|config| config.capture.clone()).unwrap_or_else(default_capture_config).install();
+    saved
+        .as_ref()
+        .map_or_else(|| ProxySettings::from_config(&ClientConfig::default()), ProxySettings::from_config)
+        .install();
+
+    #[cfg(windows)]
+    if let Commands::Service { server } = cli.command {
+        let event_log = tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .without_time()
+            .with_writer(logging::redact::Redacting::new(service::windows::EventLog::open()?));
+        logging::init(&logging_config, filter, Some(Box::new(event_log)));
+        let config = prepare_config(Some(server), None, None).await?;
+        service::windows::run(config).await?;
+        return Ok(());
+    }
+
+    if let Commands::Completions { shell } = cli.command {
+        clap_complete::generate(shell, &mut Cli::command(), env!("CARGO_PKG_NAME"), &mut std::io::stdout());
+        return Ok(());
+    }
+
+    let interactive = std::io::IsTerminal::is_terminal(&std::io::stderr());
+    let console = interactive.then(|| Box::new(logging::console_layer()) as logging::BoxedLayer);
+    logging::init(&logging_config, filter, console);
 
     match cli.command {
-        Commands::Start { server, name } => {
+        Commands::Start { server, name, enroll_token } => {
             info!("🚀 Starting AtlasConnect Client Agent");
-            start_agent(server, name).await?;
+            start_agent(server, name, enroll_token, cli.log_level).await?;
+        }
+
+        Commands::Enroll { code, server, name } => {
+            prepare_config(server, name, Some(EnrollWith::Code(code))).await?;
+            println!("Device enrolled. Run `ghostlink-client install` or `ghostlink-client start` to connect.");
         }
         
-        Commands::Install { server } => {
+        Commands::Install {
+            server,
+            enroll_token,
+            ca_cert,
+            pins,
+            account,
+            password,
+            manual_start,
+            protect_system,
+            no_new_privileges,
+            dry_run,
+        } => {
+            #[cfg(not(windows))]
+            if password.is_some() {
+                return Err("--password only applies to Windows services; systemd and launchd need none for --account".into());
+            }
+            let options = InstallOptions {
+                account,
+                #[cfg(windows)]
+                password,
+                auto_start: !manual_start,
+                protect_system,
+                no_new_privileges,
+                dry_run,
+            };
+            if dry_run {
+                ServiceManager::install(&server, &options)?;
+                return Ok(());
+            }
+
             info!("📦 Installing AtlasConnect as system service");
-            ServiceManager::install(&server)?;
+            if ca_cert.is_some() || !pins.is_empty() {
+                configure_relay_tls(&server, ca_cert, pins)?;
+            }
+            if let Some(token) = enroll_token {
+                prepare_config(Some(server.clone()), None, Some(EnrollWith::Token(token))).await?;
+            }
+            ServiceManager::install(&server, &options)?;
             info!("✅ Service installed successfully");
         }
         
@@ -106,13 +381,36 @@ async fn main() -> Result<()> {
             info!("✅ Service uninstalled successfully");
         }
         
-        Commands::Status => {
+        Commands::Status { output } => {
             let status = ServiceManager::status()?;
-            println!("Service Status: {}", status);
+            match output {
+                OutputFormat::Text => println!("Service Status: {}", status),
+                OutputFormat::Json => output::print_json(&output::ServiceReport::from(&status))?,
+            }
+        }
+
+        #[cfg(windows)]
+        Commands::Service { .. } => unreachable!("handled before logging is set up"),
+
+        #[cfg(windows)]
+        Commands::SessionHelper { pipe } => {
+            service::console::run_helper(&pipe).await?;
+        }
+        
+        Commands::Info { effective_config, output } => {
+            if effective_config {
+                show_effective_config()?;
+            } else if output == OutputFormat::Json {
+                output::print_json(&device_report(saved.as_ref()).await)?;
+            } else {
+                show_device_info();
+                show_encoder_info().await;
+                show_permissions();
+            }
         }
         
-        Commands::Info => {
-            show_device_info();
+        Commands::Doctor { server } => {
+            run_doctor(saved, server).await?;
         }
         
         Commands::Session { session_id, server_url, token } => {
@@ -123,42 +421,265 @@ async fn main() -> Result<()> {
         Commands::Toolbox { action } => {
             handle_toolbox_action(action).await?;
         }
+        
+        Commands::Join { code, server, name, idle_timeout } => {
+            join_support_session(code, server, name, idle_timeout).await?;
+        }
+        
+        Commands::Chat { action } => {
+            handle_chat_action(action)?;
+        }
+        
+        Commands::Tray { action } => {
+            handle_tray_action(action).await?;
+        }
+        
+        Commands::Recording { dir, action } => {
+            handle_recording_action(dir, action).await?;
+        }
+        
+        Commands::Logs { dir, action } => {
+            let dir = dir.unwrap_or_else(|| logging_config.log_dir());
+            handle_logs_action(dir, saved, action).await?;
+        }
+        
+        Commands::Config { .. } | Commands::Completions { .. } => unreachable!("handled before logging is set up"),
+    }
+
+    Ok(())
+}
+
+/// The persisted config with the environment's values, if the agent has
+/// one yet
+fn saved_config() -> Option<ClientConfig> {
+    let path = ClientConfig::default_path();
+    if !path.exists() {
+        return None;
+    }
+    ClientConfig::load(&path)
+        .and_then(|mut config| config.apply_env().map(|()| config))
+        .map_err(|e| eprintln!("Ignoring unreadable config {}: {}", path.display(), e))
+        .ok()
+}
+
+/// Capture settings from the environment alone, before there is a config
+fn default_capture_config() -> crate::capture::CaptureConfig {
+    let mut config = ClientConfig::default();
+    if let Err(e) = config.apply_env() {
+        eprintln!("Ignoring environment: {}", e);
+    }
+    config.capture
+}
+
+/// Load the persisted config (keeping the agent id stable across restarts),
+/// lay the environment and then the command line over it, and enroll if a
+/// token or code was given. Flags are saved for the next start; the
+/// environment's values are not.
+async fn prepare_config(
+    server_url: Option<String>,
+    device_name: Option<String>,
+    enroll_with: Option<EnrollWith>,
+) -> Result<ClientConfig> {
+    let path = ClientConfig::default_path();
+    let mut stored = if path.exists() {
+        ClientConfig::load(&path)?
+    } else {
+        ClientConfig::new(config::DEFAULT_SERVER_URL.to_string(), None)?
+    };
+    let flags = config::Overrides { server_url, hostname: device_name };
+    flags.apply(&mut stored);
+
+    let mut config = stored.clone();
+    config.apply_env()?;
+    flags.apply(&mut config);
+    config.validate()?;
+
+    if let Some(with) = enroll_with {
+        info!("Enrolling device with {}", config.server_url);
+        config.credential = Some(connection::enrollment::enroll(&config, &with).await?);
+        stored.credential = config.credential.clone();
+        info!("✅ Device enrolled");
+    } else if config.credential.is_none() {
+        warn!("Device is not enrolled; pass --enroll-token, or run `enroll --code`, to enroll it");
+    }
+
+    stored.save(&path)?;
+    Ok(config)
+}
+
+/// `config validate` and `config show`: the file, then the environment,
+/// as the agent would read them
+fn handle_config_action(action: ConfigAction) -> Result<()> {
+    let path = ClientConfig::default_path();
+    let mut config = ClientConfig::read(&path)?;
+    config.apply_env()?;
+    config.validate()?;
+    let overrides: Vec<_> = config::ENV_OVERRIDES
+        .iter()
+        .filter(|(var, _)| std::env::var(var).is_ok_and(|value| !value.trim().is_empty()))
+        .collect();
+
+    match action {
+        ConfigAction::Validate => {
+            println!("✅ {} is valid", path.display());
+            for (var, key) in overrides {
+                println!("   {} overrides {}", var, key);
+            }
+        }
+        ConfigAction::Show { output } => {
+            // Neither the signing key nor the proxy password has any
+            // business on a terminal
+            config.credential = None;
+            config.proxy_password = None;
+            match output {
+                OutputFormat::Text => {
+                    println!("# {}", path.display());
+                    for (var, key) in overrides {
+                        println!("# {} overrides {}", var, key);
+                    }
+                    println!("{}", toml::to_string_pretty(&config).map_err(anyhow::Error::from)?);
+                }
+                OutputFormat::Json => output::print_json(&config)?,
+            }
+        }
     }
+    Ok(())
+}
+
+/// Store the relay TLS settings given to `install` in the service's config
+fn configure_relay_tls(server_url: &str, ca_cert: Option<std::path::PathBuf>, pins: Vec<String>) -> Result<()> {
+    let path = ClientConfig::default_path();
+    let mut config = if path.exists() {
+        ClientConfig::load(&path)?
+    } else {
+        ClientConfig::new(server_url.to_string(), None)?
+    };
 
+    if let Some(ca_cert) = ca_cert {
+        // The service may not be able to read the installer's copy, so keep our own
+        let bundle = path.with_file_name("relay-ca.pem");
+        if let Some(parent) = bundle.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&ca_cert, &bundle)
+            .map_err(|e| format!("Failed to copy {}: {}", ca_cert.display(), e))?;
+        config.ca_bundle_path = Some(bundle);
+    }
+    if !pins.is_empty() {
+        config.pinned_cert_sha256 = pins;
+    }
+
+    // Reject a bad bundle or pin now rather than on the service's first connect
+    connection::tls::connector(&config)?;
+    config.save(&path)?;
+    info!("🔒 Relay TLS settings saved to {}", path.display());
     Ok(())
 }
 
-async fn start_agent(server_url: String, device_name: Option<String>) -> Result<()> {
-    let config = ClientConfig::new(server_url, device_name)?;
+async fn start_agent(
+    server_url: Option<String>,
+    device_name: Option<String>,
+    enroll_token: Option<String>,
+    log_level: Option<String>,
+) -> Result<()> {
+    let config = prepare_config(server_url, device_name, enroll_token.map(EnrollWith::Token)).await?;
+    // Log level, heartbeat and frame rate follow the file while the agent runs
+    let live = config::reload::watch(ClientConfig::default_path(), config.clone());
+    config::reload::follow_log_level(live.clone(), log_level);
+    run_agent(config, Some(live)).await
+}
+
+/// Run a temporary agent that registers with a support code instead of
+/// an enrollment, and is gone once the support session ends
+async fn join_support_session(
+    code: String,
+    server_url: String,
+    device_name: Option<String>,
+    idle_timeout: Option<u64>,
+) -> Result<()> {
+    // A fresh identity that is never saved, so nothing outlives the session
+    let mut config = ClientConfig::new(server_url, device_name)?;
+    let code: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    config.support_code = Some(code.clone());
+    if let Some(secs) = idle_timeout {
+        config.adhoc.idle_timeout_secs = secs;
+    }
     
+    println!("Joining support session {} on {}", code, config.server_url);
+    println!("Waiting for your technician to connect. Press Ctrl-C to leave.");
+    run_agent(config, None).await
+}
+
+async fn run_agent(config: ClientConfig, live: Option<tokio::sync::watch::Receiver<config::reload::LiveSettings>>) -> Result<()> {
     info!("Device ID: {}", config.agent_id);
     info!("Hostname: {}", config.hostname);
     info!("Connecting to: {}", config.server_url);
     
-    // Create and start the agent
+    // Create and start the agent; a joined agent is temporary and keeps
+    // no server policy or deployment commands
+    let temporary = config.support_code.is_some();
     let mut agent = Agent::new(config)?;
+    if let Some(live) = live {
+        agent = agent.with_live_settings(live);
+    }
+    if !temporary {
+        agent = agent
+            .with_policy_store(policy::PolicyStore::default_location())
+            .with_command_queue(toolbox::queue::CommandQueue::open(toolbox::queue::CommandQueue::default_path()))
+            .with_control(control::endpoint(), control::pause::PauseStore::default_location());
+    }
     
-    // Set up signal handling for graceful shutdown
-    let shutdown_signal = tokio::spawn(async {
-        tokio::signal::ctrl_c().await.unwrap();
-        info!("Received shutdown signal");
+    // Set up signal handling for graceful shutdown; the agent ends its
+    // sessions and deregisters before start() returns
+    // Without a GUI the console is the end user's chat window
+    if std::io::IsTerminal::is_terminal(&std::io::stdout()) {
+        agent.chat().open_window(std::sync::Arc::new(chat::ConsoleChat)).await;
+        info!("Technician messages appear here; reply with `ghostlink-client chat send <message>`");
+    }
+    
+    let shutdown = agent.shutdown_handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down agent...");
+        let _ = shutdown.send(()).await;
     });
     
-    // Start the agent with error recovery
-    tokio::select! {
-        result = agent.start() => {
-            match result {
-                Ok(()) => info!("Agent stopped normally"),
-                Err(e) => error!("Agent error: {}", e),
+    match agent.start().await {
+        Ok(()) => info!("Agent stopped normally"),
+        Err(e) => error!("Agent error: {}", e),
+    }
+    
+    if let Some(binary) = agent.pending_restart() {
+        info!("Restarting agent for update");
+        updater::host::restart_process(&binary)?;
+    }
+    
+    Ok(())
+}
+
+/// Wait for Ctrl-C, or on Unix for the SIGTERM systemd and init scripts stop with
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+                    _ = sigterm.recv() => info!("Received SIGTERM"),
+                }
+                return;
             }
-        }
-        _ = shutdown_signal => {
-            info!("Shutting down agent...");
-            let _ = agent.shutdown().await;
+            Err(e) => warn!("Failed to listen for SIGTERM: {}", e),
         }
     }
     
-    Ok(())
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Received shutdown signal");
 }
 
 fn show_device_info() {
@@ -184,6 +705,160 @@ fn show_device_info() {
     println!("Platform: macOS");
 }
 
+/// Print the saved config as the agent runs it, with the last policy from
+/// the server applied, followed by that policy
+fn show_effective_config() -> Result<()> {
+    let path = ClientConfig::default_path();
+    let mut config = saved_config().ok_or_else(|| format!("No config at {}", path.display()))?;
+    let store = policy::PolicyStore::default_location();
+    let policy = store.load().unwrap_or_default();
+    policy.apply(&mut config);
+    // Neither the signing key nor the proxy password has any business on
+    // a terminal
+    config.credential = None;
+    config.proxy_password = None;
+
+    println!("# {} with the policy in {} applied", path.display(), store.path().display());
+    println!("{}", toml::to_string_pretty(&config).map_err(anyhow::Error::from)?);
+    println!("# Server policy");
+    println!("{}", serde_json::to_string_pretty(&policy)?);
+    Ok(())
+}
+
+/// Print the privacy permissions the platform asks for, with what to do
+/// about missing ones
+fn show_permissions() {
+    for status in permissions::statuses() {
+        match status.remediation {
+            None => println!("{} permission: {}", status.permission, status.state),
+            Some(remediation) => println!("{} permission: {}; {}", status.permission, status.state, remediation),
+        }
+    }
+}
+
+/// Check the proxy to the relay, if one is in the way, then run the
+/// session pre-flight outside a session and print its report; fails if any
+/// check does
+async fn run_doctor(saved: Option<ClientConfig>, server: Option<String>) -> Result<()> {
+    use crate::session::preflight::{self, Check, Probe};
+    use crate::session::SessionType;
+
+    let mut config = match saved {
+        Some(config) => config,
+        None => ClientConfig::new(server.clone().unwrap_or_else(|| "wss://relay.cktechx.com".to_string()), None)?,
+    };
+    if let Some(server) = server {
+        config.server_url = server;
+    }
+
+    show_permissions();
+    // Told apart from the relay check, which fails too when the proxy does
+    let proxy_failure = match connection::proxy::probe(&config).await {
+        None => {
+            println!("Proxy: none, the relay is reached directly");
+            None
+        }
+        Some(probe) => match probe.outcome {
+            Ok(elapsed) => {
+                println!("Proxy {}: tunnel to {} opened in {} ms", probe.proxy, probe.target, elapsed.as_millis());
+                None
+            }
+            Err(e) => {
+                println!("Proxy {}: {}: {}", probe.proxy, e.kind(), e);
+                Some(format!("Proxy check failed ({}): {}", e.kind(), e))
+            }
+        },
+    };
+
+    println!("Checking session readiness against {}", config.server_url);
+    let mut probe = Probe::new(SessionType::Console, &config, None);
+    let readiness = preflight::run_checks(&mut probe, &Check::ALL).await;
+    print!("{}", readiness.table());
+
+    if let Some(failure) = proxy_failure {
+        return Err(failure.into());
+    }
+    match readiness.first_failure() {
+        Some(failure) => Err(format!("{} failed: {}", failure.check, failure.diagnostic).into()),
+        None => {
+            println!("Ready for sessions");
+            Ok(())
+        }
+    }
+}
+
+/// Probe the encoder fallback chain and show which encoder sessions use
+async fn show_encoder_info() {
+    match probe_encoder().await {
+        Ok(info) => {
+            let kind = if info.hardware_accelerated { "hardware" } else { "software" };
+            println!("Video Encoder: {} ({})", info.name, kind);
+            for skipped in &info.skipped {
+                println!("  Skipped {}: {}", skipped.encoder, skipped.reason);
+            }
+        }
+        Err(e) => println!("Video Encoder: none available ({})", e),
+    }
+}
+
+/// The encoder sessions would pick under the saved encoding policy
+async fn probe_encoder() -> Result<crate::capture::EncoderInfo> {
+    use crate::capture::encoder_factory::EncoderFactory;
+    use crate::capture::encoder_profile::EncodingPolicy;
+    use crate::capture::VideoEncoder;
+
+    let path = ClientConfig::default_path();
+    let policy = match path.exists().then(|| ClientConfig::load(&path)) {
+        Some(Ok(config)) => config.encoding,
+        _ => EncodingPolicy::default(),
+    };
+
+    let mut encoder = EncoderFactory::create_probed(policy.forced_encoder()).await?.encoder;
+    let info = encoder.get_encoder_info();
+    let _ = encoder.cleanup().await;
+    Ok(info)
+}
+
+/// `info --output json`: the registration's system info and the probed encoder
+async fn device_report(saved: Option<&ClientConfig>) -> output::DeviceReport {
+    let hostname = match saved {
+        Some(config) => config.hostname.clone(),
+        None => sysinfo::System::host_name().unwrap_or_else(|| "unknown".to_string()),
+    };
+    let encoder = match probe_encoder().await {
+        Ok(info) => Some(output::EncoderReport {
+            name: info.name,
+            hardware_accelerated: info.hardware_accelerated,
+            skipped: info.skipped,
+        }),
+        Err(e) => {
+            warn!("No video encoder available: {}", e);
+            None
+        }
+    };
+    output::DeviceReport {
+        agent_id: saved.map(|config| config.agent_id.clone()),
+        hostname,
+        os_info: connection::RelayConnection::system_info(),
+        encoder,
+        permissions: permissions::statuses(),
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Check the config file and the GHOSTLINK_* variables without starting
+    /// the agent
+    Validate,
+    
+    /// Print the config as the agent would start with it
+    Show {
+        /// text or json
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+}
+
 #[derive(Subcommand)]
 enum ToolboxAction {
     /// List all available tools
@@ -191,6 +866,9 @@ enum ToolboxAction {
         /// Filter by category
         #[arg(short, long)]
         category: Option<String>,
+        /// text or json (the tool array)
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
     },
     
     /// Add a new tool
@@ -214,21 +892,334 @@ enum ToolboxAction {
     Run {
         /// Tool name or ID
         tool: String,
+        /// Template variable, repeatable
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+        /// text (output as it comes) or json (exit_code, stdout, stderr and
+        /// duration_ms once the tool ends)
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
         /// Arguments to pass to the tool
         #[arg(last = true)]
         args: Vec<String>,
     },
 }
 
+#[derive(Subcommand)]
+enum ChatAction {
+    /// Send a message to the technician through the running agent
+    Send {
+        /// Session to send to; defaults to the agent's only session
+        #[arg(long)]
+        session: Option<String>,
+        /// Message text
+        #[arg(required = true)]
+        message: Vec<String>,
+    },
+}
+
+fn handle_chat_action(action: ChatAction) -> Result<()> {
+    match action {
+        ChatAction::Send { session, message } => {
+            chat::ChatSpool::default_location().submit(session, &message.join(" "))?;
+            println!("✅ Message queued; the agent sends it within a second");
+        }
+    }
+    Ok(())
+}
+
+#[derive(Subcommand)]
+enum TrayAction {
+    /// Print whether the agent is connected and who has a session open
+    Status {
+        /// text or json
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+    /// End a session, if your organization allows it
+    Disconnect {
+        session_id: String,
+    },
+    /// End all sessions and refuse new ones for a while
+    Pause {
+        /// How long, in hours
+        #[arg(long, default_value_t = 1)]
+        hours: u32,
+    },
+    /// Allow remote access again before the pause runs out
+    Resume,
+    /// Let a technician in with the code they gave you
+    Join {
+        code: String,
+    },
+}
+
+async fn handle_tray_action(action: Option<TrayAction>) -> Result<()> {
+    use control::{ControlRequest, ControlResponse};
+    
+    let Some(action) = action else {
+        #[cfg(feature = "viewer")]
+        return viewer::tray::run().await;
+        #[cfg(not(feature = "viewer"))]
+        return Err(error::GhostLinkError::Other(
+            "The tray window requires the 'viewer' feature; use `tray status`, `tray pause` and the other subcommands".to_string(),
+        ));
+    };
+    
+    let (request, output) = match action {
+        TrayAction::Status { output } => (ControlRequest::Status, output),
+        TrayAction::Disconnect { session_id } => (ControlRequest::Disconnect { session_id }, OutputFormat::Text),
+        TrayAction::Pause { hours } => (ControlRequest::Pause { hours }, OutputFormat::Text),
+        TrayAction::Resume => (ControlRequest::Resume, OutputFormat::Text),
+        TrayAction::Join { code } => (ControlRequest::Join { code }, OutputFormat::Text),
+    };
+    match control::client::request(&request).await? {
+        ControlResponse::Status { status } if output == OutputFormat::Json => output::print_json(&status)?,
+        ControlResponse::Status { status } => show_tray_status(&status),
+        ControlResponse::Done => println!("✅ Done"),
+        ControlResponse::Joined { code, expires_at } => {
+            println!("✅ Joined support code {}; your technician can connect until {}", code, expires_at.with_timezone(&chrono::Local).format("%H:%M"));
+        }
+        ControlResponse::Error { message } => return Err(error::GhostLinkError::Other(message)),
+    }
+    Ok(())
+}
+
+fn show_tray_status(status: &control::AgentStatus) {
+    let state = match (status.paused_until, status.connected) {
+        (Some(until), _) => format!("access paused until {}", until.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M")),
+        (None, true) => "connected".to_string(),
+        (None, false) => "not connected".to_string(),
+    };
+    println!("{}: {}", status.hostname, state);
+    if status.sessions.is_empty() {
+        println!("No active sessions");
+    }
+    for session in &status.sessions {
+        println!(
+            "  {}  {} session by {} since {}{}",
+            session.session_id,
+            session.session_type,
+            session.technician.as_deref().unwrap_or("unknown technician"),
+            session.started_at.with_timezone(&chrono::Local).format("%H:%M"),
+            if session.may_disconnect { "" } else { " (cannot be ended here)" },
+        );
+    }
+    match status.max_pause_hours {
+        Some(max) => println!("Remote access may be paused for up to {} hours", max),
+        None => println!("Your organization does not allow pausing remote access"),
+    }
+}
+
+#[derive(Subcommand)]
+enum RecordingAction {
+    /// List recordings, oldest first
+    List,
+    
+    /// Export a recording as a tar archive of its files, or as a video
+    Export {
+        /// Recording ID or session ID
+        #[arg(long)]
+        id: String,
+        /// tar (index and segments as recorded), mp4 or webm
+        #[arg(long, default_value = "tar", value_parser = parse_export_format)]
+        format: ExportFormat,
+        /// Output file (defaults to <recording id>.<format>)
+        #[arg(short, long = "out")]
+        output: Option<std::path::PathBuf>,
+        /// Burn the time and technician name into video exports
+        #[arg(long)]
+        caption: bool,
+    },
+    
+    /// Upload a recording to the server, resuming an earlier upload
+    Upload {
+        /// Recording ID or session ID
+        #[arg(long)]
+        id: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ExportFormat {
+    Archive,
+    Video(recording::VideoFormat),
+}
+
+/// Parse a `--format` flag
+fn parse_export_format(raw: &str) -> std::result::Result<ExportFormat, String> {
+    if raw.eq_ignore_ascii_case("tar") {
+        return Ok(ExportFormat::Archive);
+    }
+    raw.parse()
+        .map(ExportFormat::Video)
+        .map_err(|_| format!("expected tar, mp4 or webm, got '{}'", raw))
+}
+
+async fn handle_recording_action(dir: Option<std::path::PathBuf>, action: RecordingAction) -> Result<()> {
+    let path = ClientConfig::default_path();
+    let saved = if path.exists() { Some(ClientConfig::load(&path)?) } else { None };
+    let dir = match (dir, &saved) {
+        (Some(dir), _) => dir,
+        (None, Some(config)) => config.recording.recording_directory.clone(),
+        (None, None) => recording::RecordingConfig::default().recording_directory,
+    };
+    
+    match action {
+        RecordingAction::List => {
+            let recordings = recording::list_recordings(&dir)?;
+            if recordings.is_empty() {
+                println!("No recordings in {}", dir.display());
+                return Ok(());
+            }
+            
+            println!("{:<48} {:<25} {:>10} {:>8} {:>10}", "ID", "Started", "Duration", "Frames", "Size");
+            for r in recordings {
+                let started = chrono::DateTime::from_timestamp(r.start_time as i64, 0)
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default();
+                let duration = match r.end_time {
+                    Some(_) => format!("{:.0}s", r.duration_seconds),
+                    // Never finalized; the index reflects the last flush
+                    None => format!("{:.0}s*", r.duration_seconds),
+                };
+                println!("{:<48} {:<25} {:>10} {:>8} {:>9.1}M",
+                    r.recording_id,
+                    started,
+                    duration,
+                    r.stats.frames_recorded,
+                    r.stats.bytes_written as f64 / 1024.0 / 1024.0);
+            }
+        }
+        
+        RecordingAction::Export { id, format: ExportFormat::Archive, output, caption } => {
+            if caption {
+                warn!("--caption only applies to video exports");
+            }
+            let path = recording::export_recording(&dir, &id, output.as_deref())?;
+            println!("✅ Exported {} to {}", id, path.display());
+        }
+        
+        RecordingAction::Export { id, format: ExportFormat::Video(format), output, caption } => {
+            let options = recording::VideoExportOptions { format, caption };
+            let summary = recording::export_video(&dir, &id, output.as_deref(), options).await?;
+            println!("✅ Exported {} to {} ({} {} frames, {:.1}s{})",
+                id,
+                summary.output.display(),
+                summary.frames,
+                summary.codec,
+                summary.duration_us as f64 / 1_000_000.0,
+                if summary.complete { "" } else { ", recording was cut short" });
+        }
+        
+        RecordingAction::Upload { id } => {
+            let Some(config) = saved else {
+                return Err("No client config; enroll the device before uploading recordings".into());
+            };
+            let metadata = recording::store::find_recording(&dir, &id)?;
+            let summary = recording::upload::upload_recording(&config, &dir, &metadata).await?;
+            println!("✅ Sent {} chunks ({:.1} MiB) of {} as {}; {}",
+                summary.chunks_sent,
+                summary.bytes_sent as f64 / 1024.0 / 1024.0,
+                metadata.recording_id,
+                summary.state.id,
+                match summary.state.missing.len() {
+                    0 if summary.state.status == "complete" => "upload complete".to_string(),
+                    0 => "the rest follows once the recording stops".to_string(),
+                    missing => format!("{} chunks still missing", missing),
+                });
+        }
+    }
+    
+    Ok(())
+}
+
+#[derive(Subcommand)]
+enum LogsAction {
+    /// Bundle recent logs and send them to the server for support
+    Upload {
+        /// How far back to collect logs (e.g. 90m, 24h, 7d)
+        #[arg(long, default_value = "24h")]
+        last: String,
+        /// Add the most recent crash report
+        #[arg(long)]
+        include_crash: bool,
+        /// Save the bundle to this file instead of uploading it
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+    
+    /// Print the latest log lines and follow new ones
+    Tail {
+        /// Lines to show before following
+        #[arg(short = 'n', long, default_value_t = 50)]
+        lines: usize,
+        /// Print the raw JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+async fn handle_logs_action(dir: std::path::PathBuf, saved: Option<ClientConfig>, action: LogsAction) -> Result<()> {
+    match action {
+        LogsAction::Upload { last, include_crash, output } => {
+            let window = logging::bundle::parse_window(&last)?;
+            let files = logging::bundle::collect(&dir, window, include_crash, std::time::SystemTime::now())?;
+            if files.is_empty() {
+                warn!("No logs from the last {} in {}", last, dir.display());
+            }
+            let config = match saved {
+                Some(config) => config,
+                None => ClientConfig::new("wss://relay.cktechx.com".to_string(), None)?,
+            };
+            let manifest = logging::bundle::BundleManifest::new(&config, window);
+            let bundle = logging::bundle::write_bundle(std::io::Cursor::new(Vec::new()), &files, manifest)?.into_inner();
+            
+            match output {
+                Some(output) => {
+                    std::fs::write(&output, &bundle)?;
+                    println!("✅ Saved {} files ({:.1} KiB) to {}", files.len(), bundle.len() as f64 / 1024.0, output.display());
+                }
+                None => {
+                    let stored = logging::bundle::upload(&config, bundle).await?;
+                    println!("✅ Uploaded {} files as {}", files.len(), stored["name"].as_str().unwrap_or("a diagnostics bundle"));
+                }
+            }
+        }
+        
+        LogsAction::Tail { lines, json } => {
+            logging::tail::follow(&dir.join(logging::LOG_FILE), lines, json).await?;
+        }
+    }
+    
+    Ok(())
+}
+
 async fn launch_session_window(session_id: String, server_url: String, token: String) -> Result<()> {
     info!("Launching session window for {} via {}", session_id, server_url);
-    
+
+    // Refuse stale or copied launch links before anything attaches
+    let grant = crate::session::token::validate(&server_url, &session_id, &token).await
+        .map_err(|e| format!("Session token was rejected: {}", e))?;
+    info!("Session token allows {} until {}", grant.scopes.join(", "), grant.expires_at());
+
     // Initialize toolbox for this session
-    use crate::toolbox::{ToolboxManager, ToolboxConfig};
+    use crate::toolbox::{ToolboxManager, ToolboxConfig, SERVER_SYNC_INTERVAL};
     use crate::session::SessionWindow;
     
-    let toolbox_config = ToolboxConfig::default();
-    let toolbox = ToolboxManager::new(toolbox_config).await?;
+    let mut toolbox_config = ToolboxConfig {
+        server_url: Some(server_url.clone()),
+        auth_token: Some(token.clone()),
+        ..saved_config().map(|config| config.toolbox).unwrap_or_default()
+    };
+    if let Some(policy) = policy::PolicyStore::default_location().load() {
+        policy.apply_toolbox(&mut toolbox_config);
+    }
+    let sync_enabled = toolbox_config.server_sync_enabled;
+    let toolbox = std::sync::Arc::new(ToolboxManager::new(toolbox_config).await?);
+    if sync_enabled {
+        toolbox.start_background_sync(SERVER_SYNC_INTERVAL);
+    }
     
     // Create ScreenConnect-style session window
     let session_window = SessionWindow::new(session_id.clone(), server_url.clone(), token.clone(), toolbox).await?;
@@ -237,8 +1228,8 @@ async fn launch_session_window(session_id: String, server_url: String, token: St
     
     #[cfg(feature = "viewer")]
     {
-        // Launch desktop window with tabbed interface + toolbox
-        launch_tabbed_session_window(session_window).await?;
+        // Desktop window with the tabs, the remote screen and the toolbox
+        viewer::run(session_window).await?;
     }
     
     #[cfg(not(feature = "viewer"))]
@@ -248,7 +1239,9 @@ async fn launch_session_window(session_id: String, server_url: String, token: St
         
         // Demonstrate session window capabilities
         session_window.send_message("Session started from console".to_string(), true).await?;
-        session_window.add_note("Console session example".to_string(), "System".to_string(), false).await?;
+        if let Err(e) = session_window.add_note("Console session example".to_string(), "System".to_string(), false, None).await {
+            warn!("Failed to add note: {}", e);
+        }
         
         let summary = session_window.get_session_summary().await;
         for (key, value) in summary {
@@ -263,75 +1256,30 @@ async fn launch_session_window(session_id: String, server_url: String, token: St
     Ok(())
 }
 
-#[cfg(feature = "viewer")]
-async fn launch_tabbed_session_window(
-    mut session_window: crate::session::SessionWindow
-) -> Result<()> {
-    use crate::session::SessionTab;
-    
-    info!("Launching ScreenConnect-style tabbed session window");
-    
-    // Demonstrate tab functionality
-    session_window.switch_tab(SessionTab::General).await;
-    info!("Switched to General tab");
-    
-    session_window.switch_tab(SessionTab::Commands).await;
-    info!("Switched to Commands tab");
-    
-    // Execute example command with ScreenConnect-style modifiers
-    let cmd_result = session_window.execute_command(
-        "systeminfo".to_string(),
-        Some(30), // #timeout 30
-        Some(16384), // #maxlength 16384
-        Some("cmd".to_string())
-    ).await?;
-    
-    info!("Command executed: {} ({}ms)", cmd_result.command, cmd_result.duration_ms);
-    
-    // Enable backstage mode (like ScreenConnect)
-    session_window.enable_backstage_mode().await?;
-    info!("Backstage mode enabled - user won't see remote control");
-    
-    // Launch a tool from toolbox
-    session_window.launch_tool("System Info".to_string(), vec![]).await?;
-    
-    // Add session note
-    session_window.add_note(
-        "Troubleshooting user's system - investigating slow performance".to_string(),
-        "Technician".to_string(),
-        false
-    ).await?;
-    
-    // Switch to Timeline to show activity
-    session_window.switch_tab(SessionTab::Timeline).await;
-    info!("Session timeline updated with all activities");
-    
-    // TODO: Integrate with Tauri/winit for actual GUI:
-    // - Tabbed interface (Start/General/Timeline/Messages/Commands/Notes)
-    // - Remote desktop display in Start tab
-    // - Toolbox sidebar with double-click tool launch
-    // - Control buttons (blank screen, suspend input, file transfer)
-    // - Real-time command execution in Commands tab
-    // - Chat in Messages tab
-    
-    info!("Session window ready - GUI implementation pending");
-    
-    // Keep window alive
-    tokio::signal::ctrl_c().await.unwrap();
-    info!("Session window closed");
-    
-    Ok(())
+/// Parse a `--var name=value` flag
+fn parse_var(raw: &str) -> std::result::Result<(String, String), String> {
+    raw.split_once('=')
+        .map(|(name, value)| (name.trim().to_string(), value.to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", raw))
 }
 
 async fn handle_toolbox_action(action: ToolboxAction) -> Result<()> {
-    use crate::toolbox::{ToolboxManager, ToolboxConfig, Tool, ToolCategory};
+    use crate::toolbox::{ToolboxManager, Tool, ToolCategory};
+    use crate::error::ToolError;
+    use crate::toolbox::execution::{ToolExitStatus, ToolOutputEvent};
+    use crate::toolbox::template;
+    use std::collections::HashMap;
     use uuid::Uuid;
     
-    let config = ToolboxConfig::default();
-    let mut toolbox = ToolboxManager::new(config).await?;
+    let mut config = saved_config().map(|config| config.toolbox).unwrap_or_default();
+    if let Some(policy) = policy::PolicyStore::default_location().load() {
+        policy.apply_toolbox(&mut config);
+    }
+    let toolbox = ToolboxManager::new(config).await?;
     
     match action {
-        ToolboxAction::List { category } => {
+        ToolboxAction::List { category, output } => {
             let tools = if let Some(cat_str) = category {
                 let category = match cat_str.to_lowercase().as_str() {
                     "system" => ToolCategory::System,
@@ -340,17 +1288,16 @@ async fn handle_toolbox_action(action: ToolboxAction) -> Result<()> {
                     "monitoring" => ToolCategory::Monitoring,
                     "development" => ToolCategory::Development,
                     "custom" => ToolCategory::Custom,
-                    _ => {
-                        warn!("Unknown category: {}", cat_str);
-                        return Ok(());
-                    }
+                    _ => return Err(format!("Unknown category: {}", cat_str).into()),
                 };
                 toolbox.list_tools_by_category(&category)
             } else {
                 toolbox.list_tools()
             };
             
-            if tools.is_empty() {
+            if output == OutputFormat::Json {
+                output::print_json(&tools)?;
+            } else if tools.is_empty() {
                 println!("No tools found");
             } else {
                 println!("Available tools:");
@@ -374,6 +1321,7 @@ async fn handle_toolbox_action(action: ToolboxAction) -> Result<()> {
                 requires_admin: false,
                 auto_update: false,
                 server_managed: false,
+                args_template: Vec::new(),
             };
             
             toolbox.add_tool(tool).await?;
@@ -382,43 +1330,122 @@ async fn handle_toolbox_action(action: ToolboxAction) -> Result<()> {
         
         ToolboxAction::Remove { tool } => {
             if let Ok(uuid) = Uuid::parse_str(&tool) {
-                toolbox.remove_tool(&uuid)?;
+                toolbox.remove_tool(&uuid).await?;
                 info!("Removed tool: {}", tool);
             } else {
-                let tools = toolbox.list_tools();
-                if let Some(tool_id) = tools.iter().find(|t| t.name == tool).map(|t| t.id) {
-                    drop(tools); // Release the borrow
-                    toolbox.remove_tool(&tool_id)?;
+                if let Some(tool_id) = toolbox.list_tools().iter().find(|t| t.name == tool).map(|t| t.id) {
+                    toolbox.remove_tool(&tool_id).await?;
                     info!("Removed tool: {}", tool);
                 } else {
-                    warn!("Tool not found: {}", tool);
+                    return Err(ToolError::NotFound { tool }.into());
                 }
             }
         }
         
-        ToolboxAction::Run { tool, args } => {
-            let tool_id = if let Ok(uuid) = Uuid::parse_str(&tool) {
-                uuid
-            } else {
-                let tools = toolbox.list_tools();
-                if let Some(found_tool) = tools.iter().find(|t| t.name == tool) {
-                    found_tool.id
-                } else {
-                    warn!("Tool not found: {}", tool);
-                    return Ok(());
-                }
+        ToolboxAction::Run { tool, vars, output, args } => {
+            let found = match Uuid::parse_str(&tool) {
+                Ok(uuid) => toolbox.get_tool(&uuid),
+                Err(_) => toolbox.list_tools().into_iter().find(|t| t.name == tool),
+            };
+            let Some(found_tool) = found else {
+                return Err(ToolError::NotFound { tool }.into());
             };
+            let tool_id = found_tool.id;
             
-            match toolbox.execute_tool(&tool_id, args).await {
-                Ok(output) => {
-                    println!("{}", output);
-                }
-                Err(e) => {
-                    error!("Tool execution failed: {}", e);
+            let vars: HashMap<String, String> = vars.into_iter().collect();
+            let missing = template::missing_required(&found_tool.args_template, &vars);
+            if !missing.is_empty() {
+                eprintln!("{}", template::usage(&found_tool.name, &found_tool.args_template));
+                return Err(format!("Missing variables: {}", missing.iter().map(|arg| arg.name.as_str()).collect::<Vec<_>>().join(", ")).into());
+            }
+            
+            let started = std::time::Instant::now();
+            let mut handle = toolbox.execute_tool_streaming(&tool_id, &vars, args).await?;
+            let mut report = output::ToolRunReport::default();
+            let mut ended = ToolExitStatus::Cancelled;
+            
+            loop {
+                let event = tokio::select! {
+                    event = handle.next_event() => event,
+                    _ = tokio::signal::ctrl_c() => {
+                        handle.cancel();
+                        continue;
+                    }
+                };
+                
+                match event {
+                    Some(ToolOutputEvent::Stdout(line)) if output == OutputFormat::Json => report.push_stdout(&line),
+                    Some(ToolOutputEvent::Stderr(line)) if output == OutputFormat::Json => report.push_stderr(&line),
+                    Some(ToolOutputEvent::Stdout(line)) => println!("{}", line),
+                    Some(ToolOutputEvent::Stderr(line)) => eprintln!("{}", line),
+                    Some(ToolOutputEvent::Truncated) => {
+                        report.truncated = true;
+                        warn!("Output limit reached, further output discarded");
+                    }
+                    Some(ToolOutputEvent::Finished(status)) => {
+                        ended = status;
+                        break;
+                    }
+                    None => break,
                 }
             }
+            
+            if output == OutputFormat::Json {
+                report.finish(ended, started.elapsed().as_millis() as u64);
+                output::print_json(&report)?;
+            }
+            // The tool's own failure is reported like any other, so a
+            // script sees a non-zero exit whichever output it asked for
+            let reason = match ended {
+                ToolExitStatus::Exited(Some(0)) => return Ok(()),
+                ToolExitStatus::Exited(Some(code)) => format!("exited with code {}", code),
+                ToolExitStatus::Exited(None) => "killed by a signal".to_string(),
+                ToolExitStatus::TimedOut => "timed out and was stopped".to_string(),
+                ToolExitStatus::Cancelled => "cancelled".to_string(),
+            };
+            return Err(ToolError::ExecutionFailed { tool: found_tool.name, reason }.into());
         }
     }
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_output_flag() {
+        let cli = Cli::try_parse_from(["ghostlink-client", "status", "-o", "json"]).unwrap();
+        assert!(matches!(cli.command, Commands::Status { output: OutputFormat::Json }));
+
+        let cli = Cli::try_parse_from(["ghostlink-client", "toolbox", "run", "ping", "--output", "json", "--", "-c", "1"]).unwrap();
+        match cli.command {
+            Commands::Toolbox { action: ToolboxAction::Run { tool, output, args, .. } } => {
+                assert_eq!(tool, "ping");
+                assert_eq!(output, OutputFormat::Json);
+                assert_eq!(args, ["-c", "1"]);
+            }
+            _ => panic!("expected toolbox run"),
+        }
+
+        let cli = Cli::try_parse_from(["ghostlink-client", "info"]).unwrap();
+        assert!(matches!(cli.command, Commands::Info { output: OutputFormat::Text, .. }));
+        assert!(Cli::try_parse_from(["ghostlink-client", "info", "-o", "yaml"]).is_err());
+        assert!(Cli::try_parse_from(["ghostlink-client", "info", "--effective-config", "-o", "json"]).is_err());
+    }
+
+    #[test]
+    fn test_completions() {
+        let cli = Cli::try_parse_from(["ghostlink-client", "completions", "zsh"]).unwrap();
+        let Commands::Completions { shell } = cli.command else { panic!("expected completions") };
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut Cli::command(), "ghostlink-client", &mut script);
+        assert!(String::from_utf8(script).unwrap().contains("toolbox"));
+    }
+}
@@ -0,0 +1,491 @@
+//! Local network adapters, Tailscale and Wake-on-LAN
+//!
+//! The agent reports its adapters when it registers so the server can find
+//! another agent on the same subnet to wake it later, and sends magic
+//! packets when the server picks it as that proxy for a sleeping device.
+//! It also reports its tailnet address, so peers on the same tailnet can
+//! connect over it.
+//!
+//! Sockets of both address families come from [`dual_stack_udp`]: one IPv6
+//! socket that IPv4 traffic reaches too, as v4-mapped addresses.
+//! [`socket_target`] maps IPv4 destinations accordingly, and
+//! `IpAddr::to_canonical` turns the sources of received datagrams back.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+/// Port magic packets go to; the NIC ignores it, but 9 (discard) is customary
+pub const WAKE_PORT: u16 = 9;
+
+/// All-nodes group magic packets go to on IPv6 links
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// How long `tailscale status` may take before Tailscale counts as absent
+const TAILSCALE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A network adapter as reported to the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    /// Hardware address, `aa:bb:cc:dd:ee:ff`; absent on adapters without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    /// IPv4 addresses in CIDR notation, e.g. `192.168.1.20/24`
+    #[serde(default)]
+    pub ipv4: Vec<String>,
+    /// IPv6 addresses in CIDR notation, e.g. `2001:db8::20/64`
+    #[serde(default)]
+    pub ipv6: Vec<String>,
+    /// OS index of the adapter, which scopes link-local multicast; not reported
+    #[serde(skip)]
+    pub index: u32,
+}
+
+/// This machine's address in a tailnet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TailnetAddress {
+    /// MagicDNS suffix of the tailnet, e.g. `tail1234.ts.net`, which tells
+    /// tailnets apart
+    pub tailnet: String,
+    pub address: IpAddr,
+}
+
+/// Parse `aa:bb:cc:dd:ee:ff`, `aa-bb-cc-dd-ee-ff` or `aabb.ccdd.eeff`
+pub fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let digits: String = mac.chars().filter(|c| !matches!(c, ':' | '-' | '.')).collect();
+    if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid MAC address: {}", mac);
+    }
+    let mut bytes = [0u8; 6];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(bytes)
+}
+
+/// Six `0xff` bytes followed by the MAC sixteen times
+pub fn magic_packet(mac: [u8; 6]) -> [u8; 102] {
+    let mut packet = [0xff; 102];
+    for chunk in packet[6..].chunks_exact_mut(6) {
+        chunk.copy_from_slice(&mac);
+    }
+    packet
+}
+
+/// Broadcast a magic packet for `mac` on the local segment, and to the
+/// subnet's directed broadcast address when the server gave one. The
+/// limited broadcast only leaves through the default route's adapter on some
+/// systems, so the directed one is what reaches the other NICs' subnets.
+/// Every adapter with IPv6 also gets it through `ff02::1`.
+pub async fn send_magic_packet(mac: &str, broadcast: Option<Ipv4Addr>) -> Result<()> {
+    let mut targets = vec![SocketAddr::from((Ipv4Addr::BROADCAST, WAKE_PORT))];
+    targets.extend(broadcast.map(|address| SocketAddr::from((address, WAKE_PORT))));
+    targets.extend(multicast_targets(&interfaces()));
+    send_magic_packet_to(parse_mac(mac)?, &targets).await
+}
+
+/// `ff02::1` scoped to each adapter that has an IPv6 address
+fn multicast_targets(interfaces: &[NetworkInterface]) -> Vec<SocketAddr> {
+    interfaces
+        .iter()
+        .filter(|interface| interface.index != 0 && !interface.ipv6.is_empty())
+        .map(|interface| SocketAddr::V6(SocketAddrV6::new(ALL_NODES, WAKE_PORT, 0, interface.index)))
+        .collect()
+}
+
+async fn send_magic_packet_to(mac: [u8; 6], targets: &[SocketAddr]) -> Result<()> {
+    // Broadcasts need a plain IPv4 socket; IPv6 targets are skipped on
+    // systems without IPv6
+    let ipv4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.context("Failed to open UDP socket")?;
+    ipv4.set_broadcast(true)?;
+    let ipv6 = match targets.iter().any(SocketAddr::is_ipv6) {
+        true => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok(),
+        false => None,
+    };
+    let packet = magic_packet(mac);
+
+    // One reachable target is enough; the rest are best effort
+    let mut last_error = None;
+    let mut sent = 0;
+    for target in targets {
+        let socket = match target {
+            SocketAddr::V4(_) => &ipv4,
+            SocketAddr::V6(_) => match &ipv6 {
+                Some(socket) => socket,
+                None => continue,
+            },
+        };
+        match socket.send_to(&packet, target).await {
+            Ok(_) => sent += 1,
+            Err(e) => last_error = Some(anyhow::Error::new(e).context(format!("Failed to send magic packet to {}", target))),
+        }
+    }
+    match last_error {
+        Some(e) if sent == 0 => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Adapters with a hardware address or an IP address, loopback excluded
+pub fn interfaces() -> Vec<NetworkInterface> {
+    let mut interfaces = platform_interfaces();
+    for interface in &mut interfaces {
+        interface.ipv4.retain(|cidr| !cidr.starts_with("127."));
+        interface.ipv6.retain(|cidr| !cidr.starts_with("::1/"));
+        if interface.mac.as_deref() == Some("00:00:00:00:00:00") {
+            interface.mac = None;
+        }
+    }
+    interfaces.retain(|interface| interface.mac.is_some() || !interface.ipv4.is_empty() || !interface.ipv6.is_empty());
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+/// A UDP socket on `port` for both address families, or an IPv4 one where
+/// the system has no IPv6
+pub fn dual_stack_udp(port: u16) -> std::io::Result<UdpSocket> {
+    let ipv6 = || -> std::io::Result<std::net::UdpSocket> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok(socket.into())
+    };
+    let socket = match ipv6() {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("No IPv6 socket ({}), using IPv4 only", e);
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+            socket.set_nonblocking(true)?;
+            socket
+        }
+    };
+    UdpSocket::from_std(socket)
+}
+
+/// `target` as a socket bound to `local` addresses it: IPv4 targets become
+/// v4-mapped on IPv6 sockets. `None` when an IPv4 socket can't reach it.
+pub fn socket_target(local: SocketAddr, target: SocketAddr) -> Option<SocketAddr> {
+    match (local, target) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => Some(SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port()))),
+        (SocketAddr::V4(_), SocketAddr::V6(v6)) => {
+            v6.ip().to_ipv4_mapped().map(|ip| SocketAddr::from((ip, v6.port())))
+        }
+        _ => Some(target),
+    }
+}
+
+/// Host of a URL as name resolution takes it, IPv6 literals without brackets
+pub fn url_host(url: &url::Url) -> Option<String> {
+    match url.host()? {
+        url::Host::Ipv6(ip) => Some(ip.to_string()),
+        host => Some(host.to_string()),
+    }
+}
+
+/// The tailnet address of this machine, when Tailscale is installed and
+/// logged in
+pub async fn tailnet() -> Option<TailnetAddress> {
+    let output = tokio::process::Command::new("tailscale")
+        .args(["status", "--json"])
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(TAILSCALE_TIMEOUT, output).await {
+        Ok(Ok(output)) if output.status.success() => parse_tailnet(&output.stdout),
+        Ok(Ok(output)) => {
+            debug!("Tailscale is not running: {}", String::from_utf8_lossy(&output.stderr).trim());
+            None
+        }
+        Ok(Err(e)) => {
+            debug!("Tailscale is not available: {}", e);
+            None
+        }
+        Err(_) => {
+            debug!("tailscale status did not answer within {:?}", TAILSCALE_TIMEOUT);
+            None
+        }
+    }
+}
+
+/// Tailnet address from the output of `tailscale status --json`
+fn parse_tailnet(json: &[u8]) -> Option<TailnetAddress> {
+    #[derive(Default, Deserialize)]
+    #[serde(rename_all = "PascalCase", default)]
+    struct Status {
+        backend_state: String,
+        #[serde(rename = "MagicDNSSuffix")]
+        magic_dns_suffix: String,
+        #[serde(rename = "TailscaleIPs")]
+        tailscale_ips: Option<Vec<IpAddr>>,
+    }
+
+    let status: Status = serde_json::from_slice(json).ok()?;
+    if status.backend_state != "Running" || status.magic_dns_suffix.is_empty() {
+        return None;
+    }
+    let addresses = status.tailscale_ips.unwrap_or_default();
+    let address = *addresses.iter().find(|address| address.is_ipv4()).or(addresses.first())?;
+    Some(TailnetAddress { tailnet: status.magic_dns_suffix, address })
+}
+
+/// Addresses from `getifaddrs`, hardware addresses from sysinfo
+#[cfg(unix)]
+fn platform_interfaces() -> Vec<NetworkInterface> {
+    use std::collections::BTreeMap;
+    use std::ffi::CStr;
+
+    // IPv4 and IPv6 addresses per adapter name
+    let mut addresses: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } == 0 {
+        let mut cursor = list;
+        while !cursor.is_null() {
+            // SAFETY: getifaddrs returned a valid list that is freed below
+            let entry = unsafe { &*cursor };
+            cursor = entry.ifa_next;
+            if entry.ifa_addr.is_null() || entry.ifa_netmask.is_null() {
+                continue;
+            }
+            let name = unsafe { CStr::from_ptr(entry.ifa_name) }.to_string_lossy().into_owned();
+            match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+                libc::AF_INET => {
+                    let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                    let netmask = unsafe { &*(entry.ifa_netmask as *const libc::sockaddr_in) };
+                    let ip = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+                    let prefix = u32::from_be(netmask.sin_addr.s_addr).count_ones();
+                    addresses.entry(name).or_default().0.push(format!("{}/{}", ip, prefix));
+                }
+                libc::AF_INET6 => {
+                    let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                    let netmask = unsafe { &*(entry.ifa_netmask as *const libc::sockaddr_in6) };
+                    let ip = Ipv6Addr::from(address.sin6_addr.s6_addr);
+                    let prefix: u32 = netmask.sin6_addr.s6_addr.iter().map(|byte| byte.count_ones()).sum();
+                    addresses.entry(name).or_default().1.push(format!("{}/{}", ip, prefix));
+                }
+                _ => {}
+            }
+        }
+        unsafe { libc::freeifaddrs(list) };
+    }
+
+    let networks = sysinfo::Networks::new_with_refreshed_list();
+    for name in networks.list().keys() {
+        addresses.entry(name.clone()).or_default();
+    }
+    addresses
+        .into_iter()
+        .map(|(name, (ipv4, ipv6))| NetworkInterface {
+            mac: networks.list().get(&name).map(|data| data.mac_address().to_string()),
+            index: std::ffi::CString::new(name.as_str())
+                .map(|c_name| unsafe { libc::if_nametoindex(c_name.as_ptr()) })
+                .unwrap_or(0),
+            name,
+            ipv4,
+            ipv6,
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+fn platform_interfaces() -> Vec<NetworkInterface> {
+    use windows::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, NO_ERROR};
+    use windows::Win32::NetworkManagement::IpHelper::{
+        GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST,
+        IP_ADAPTER_ADDRESSES_LH,
+    };
+    use windows::Win32::NetworkManagement::Ndis::IfOperStatusUp;
+    use windows::Win32::Networking::WinSock::{AF_INET, AF_INET6, AF_UNSPEC, SOCKADDR_IN, SOCKADDR_IN6};
+
+    let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+    let mut size = 16 * 1024u32;
+    // u64 elements keep the adapter structs aligned
+    let mut buffer = vec![0u64; size as usize / 8];
+    loop {
+        let result = unsafe {
+            GetAdaptersAddresses(
+                AF_UNSPEC.0 as u32,
+                flags,
+                None,
+                Some(buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH),
+                &mut size,
+            )
+        };
+        if result == ERROR_BUFFER_OVERFLOW.0 {
+            buffer = vec![0u64; (size as usize).div_ceil(8)];
+            continue;
+        }
+        if result != NO_ERROR.0 {
+            return Vec::new();
+        }
+        break;
+    }
+
+    let mut interfaces = Vec::new();
+    let mut adapter = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+    while !adapter.is_null() {
+        // SAFETY: GetAdaptersAddresses filled `buffer` with a linked list of adapters
+        let entry = unsafe { &*adapter };
+        adapter = entry.Next;
+        if entry.OperStatus != IfOperStatusUp {
+            continue;
+        }
+
+        let mac = (entry.PhysicalAddressLength == 6).then(|| {
+            entry.PhysicalAddress[..6].iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":")
+        });
+        let (mut ipv4, mut ipv6) = (Vec::new(), Vec::new());
+        let mut unicast = entry.FirstUnicastAddress;
+        while !unicast.is_null() {
+            let address = unsafe { &*unicast };
+            unicast = address.Next;
+            let sockaddr = address.Address.lpSockaddr;
+            if sockaddr.is_null() {
+                continue;
+            }
+            let family = unsafe { (*sockaddr).sa_family };
+            if family == AF_INET {
+                let sockaddr = unsafe { &*(sockaddr as *const SOCKADDR_IN) };
+                let ip = Ipv4Addr::from(u32::from_be(unsafe { sockaddr.sin_addr.S_un.S_addr }));
+                ipv4.push(format!("{}/{}", ip, address.OnLinkPrefixLength));
+            } else if family == AF_INET6 {
+                let sockaddr = unsafe { &*(sockaddr as *const SOCKADDR_IN6) };
+                let ip = Ipv6Addr::from(unsafe { sockaddr.sin6_addr.u.Byte });
+                ipv6.push(format!("{}/{}", ip, address.OnLinkPrefixLength));
+            }
+        }
+
+        interfaces.push(NetworkInterface {
+            name: unsafe { entry.FriendlyName.to_string() }.unwrap_or_default(),
+            mac,
+            ipv4,
+            ipv6,
+            index: entry.Ipv6IfIndex,
+        });
+    }
+    interfaces
+}
+
+#[cfg(not(any(unix, windows)))]
+fn platform_interfaces() -> Vec<NetworkInterface> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magic_packet() {
+        let mac = parse_mac("AA-BB-CC-DD-EE-01").unwrap();
+        assert_eq!(mac, [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x01]);
+        assert_eq!(parse_mac("aabb.ccdd.ee01").unwrap(), mac);
+        for invalid in ["", "aa:bb:cc:dd:ee", "aa:bb:cc:dd:ee:0g", "aa:bb:cc:dd:ee:01:02"] {
+            assert!(parse_mac(invalid).is_err(), "{}", invalid);
+        }
+
+        let packet = magic_packet(mac);
+        assert_eq!(&packet[..6], &[0xff; 6]);
+        assert!(packet[6..].chunks(6).all(|chunk| chunk == mac));
+        assert_eq!(packet[6..].chunks(6).count(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_magic_packet_is_sent() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let unreachable = SocketAddr::from(([0, 0, 0, 0], 0));
+        let mac = parse_mac("aa:bb:cc:dd:ee:01").unwrap();
+        send_magic_packet_to(mac, &[unreachable, receiver.local_addr().unwrap()]).await.unwrap();
+
+        let mut buffer = [0u8; 256];
+        let (len, _) = receiver.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], &magic_packet(mac)[..]);
+    }
+
+    #[test]
+    fn test_parse_tailnet() {
+        let status = br#"{
+            "BackendState": "Running",
+            "TailscaleIPs": ["fd7a:115c:a1e0::7", "100.64.0.7"],
+            "MagicDNSSuffix": "tail1234.ts.net",
+            "Peer": {}
+        }"#;
+        assert_eq!(
+            parse_tailnet(status),
+            Some(TailnetAddress { tailnet: "tail1234.ts.net".to_string(), address: "100.64.0.7".parse().unwrap() })
+        );
+
+        let logged_out = br#"{"BackendState": "NeedsLogin", "TailscaleIPs": null, "MagicDNSSuffix": ""}"#;
+        assert_eq!(parse_tailnet(logged_out), None);
+        assert_eq!(parse_tailnet(b"failed to connect to local tailscaled"), None);
+    }
+
+    #[tokio::test]
+    async fn test_magic_packet_reaches_ipv6_targets() {
+        let Ok(receiver) = UdpSocket::bind("[::1]:0").await else { return };
+        let mac = parse_mac("aa:bb:cc:dd:ee:01").unwrap();
+        send_magic_packet_to(mac, &[receiver.local_addr().unwrap()]).await.unwrap();
+
+        let mut buffer = [0u8; 256];
+        let (len, _) = receiver.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], &magic_packet(mac)[..]);
+    }
+
+    #[test]
+    fn test_multicast_targets() {
+        let interface = |index: u32, ipv6: &[&str]| NetworkInterface {
+            name: format!("eth{}", index),
+            mac: Some("aa:bb:cc:dd:ee:01".to_string()),
+            ipv4: vec!["192.168.1.20/24".to_string()],
+            ipv6: ipv6.iter().map(|cidr| cidr.to_string()).collect(),
+            index,
+        };
+        let targets = multicast_targets(&[interface(2, &["fe80::20/64"]), interface(3, &[]), interface(0, &["fe80::21/64"])]);
+        assert_eq!(targets, vec![SocketAddr::V6(SocketAddrV6::new(ALL_NODES, WAKE_PORT, 0, 2))]);
+        assert_eq!(targets[0].to_string(), "[ff02::1%2]:9");
+    }
+
+    #[test]
+    fn test_address_notation() {
+        let ipv6_socket: SocketAddr = "[::]:40000".parse().unwrap();
+        let ipv4_socket: SocketAddr = "0.0.0.0:40000".parse().unwrap();
+        let ipv4: SocketAddr = "192.0.2.7:3478".parse().unwrap();
+        let ipv6: SocketAddr = "[2001:db8::7]:3478".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.0.2.7]:3478".parse().unwrap();
+
+        assert_eq!(socket_target(ipv6_socket, ipv4), Some(mapped));
+        assert_eq!(socket_target(ipv6_socket, ipv6), Some(ipv6));
+        assert_eq!(socket_target(ipv4_socket, ipv4), Some(ipv4));
+        assert_eq!(socket_target(ipv4_socket, mapped), Some(ipv4));
+        assert_eq!(socket_target(ipv4_socket, ipv6), None);
+        assert_eq!(SocketAddr::new(mapped.ip().to_canonical(), mapped.port()), ipv4);
+
+        for (url, expected) in [
+            ("wss://[2001:db8::7]:8443/relay/ws", "2001:db8::7"),
+            ("wss://192.0.2.7:8443", "192.0.2.7"),
+            ("wss://relay.example.com", "relay.example.com"),
+        ] {
+            let parsed = url::Url::parse(url).unwrap();
+            let host = url_host(&parsed).unwrap();
+            assert_eq!(host, expected);
+            // Round trip through resolution notation and back into a URL
+            let port = parsed.port_or_known_default().unwrap();
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                assert!(url.contains(&SocketAddr::new(ip, port).to_string()), "{}", url);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interfaces_skip_loopback() {
+        for interface in interfaces() {
+            assert!(interface.ipv4.iter().all(|cidr| !cidr.starts_with("127.")), "{:?}", interface);
+            assert!(interface.ipv6.iter().all(|cidr| !cidr.starts_with("::1/")), "{:?}", interface);
+            assert!(interface.mac.is_some() || !interface.ipv4.is_empty() || !interface.ipv6.is_empty());
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Machine-readable command output
+//!
+//! `status`, `info` and `toolbox list|run` take `--output json` for scripts
+//! that wrap the client. The JSON is the only thing written to stdout; logs
+//! go to stderr and failures still exit with the status from
+//! [`GhostLinkError::exit_code`](crate::error::GhostLinkError::exit_code).
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::capture::encoder_factory::SkippedEncoder;
+use crate::error::Result;
+use crate::permissions::PermissionStatus;
+use crate::service::{ServiceState, ServiceStatus};
+use crate::toolbox::execution::ToolExitStatus;
+
+/// How a command prints its result
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Prose for people
+    #[default]
+    Text,
+    /// One JSON document on stdout
+    Json,
+}
+
+/// Print `value` as pretty JSON on stdout
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// `status --output json`
+#[derive(Debug, Serialize)]
+pub struct ServiceReport {
+    /// not_installed, running, starting, stopping, stopped, paused, failed or unknown
+    pub state: &'static str,
+    /// What the service manager said, for an unknown state
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub uptime_secs: Option<i64>,
+    pub pid: Option<u32>,
+    pub last_exit_code: Option<i32>,
+}
+
+impl From<&ServiceStatus> for ServiceReport {
+    fn from(status: &ServiceStatus) -> Self {
+        let (state, detail) = match &status.state {
+            ServiceState::NotInstalled => ("not_installed", None),
+            ServiceState::Running => ("running", None),
+            ServiceState::Starting => ("starting", None),
+            ServiceState::Stopping => ("stopping", None),
+            ServiceState::Stopped => ("stopped", None),
+            ServiceState::Paused => ("paused", None),
+            ServiceState::Failed => ("failed", None),
+            ServiceState::Unknown(detail) => ("unknown", Some(detail.clone())),
+        };
+        Self {
+            state,
+            detail,
+            since: status.since,
+            uptime_secs: status.uptime().map(|uptime| uptime.num_seconds()),
+            pid: status.pid,
+            last_exit_code: status.last_exit_code,
+        }
+    }
+}
+
+/// `info --output json`: what the agent registers with, plus the encoder
+/// sessions would use
+#[derive(Debug, Serialize)]
+pub struct DeviceReport {
+    /// Saved agent id; absent before the first `start` or `enroll`
+    pub agent_id: Option<String>,
+    pub hostname: String,
+    /// The registration's `os_info`
+    pub os_info: serde_json::Value,
+    /// Absent when no encoder works on this machine
+    pub encoder: Option<EncoderReport>,
+    /// Privacy permissions the platform asks for; empty off macOS
+    pub permissions: Vec<PermissionStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EncoderReport {
+    pub name: String,
+    pub hardware_accelerated: bool,
+    /// Encoders tried first and why they were passed over
+    pub skipped: Vec<SkippedEncoder>,
+}
+
+/// `toolbox run --output json`
+#[derive(Debug, Default, Serialize)]
+pub struct ToolRunReport {
+    /// The tool's exit code; null if it was killed, timed out or was cancelled
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration_ms: u64,
+    /// exited, timed_out or cancelled
+    pub status: &'static str,
+    /// Whether output past the limit was discarded
+    pub truncated: bool,
+}
+
+impl ToolRunReport {
+    /// Append a line the tool printed to stdout
+    pub fn push_stdout(&mut self, line: &str) {
+        self.stdout.push_str(line);
+        self.stdout.push('\n');
+    }
+
+    /// Append a line the tool printed to stderr
+    pub fn push_stderr(&mut self, line: &str) {
+        self.stderr.push_str(line);
+        self.stderr.push('\n');
+    }
+
+    /// Record how the tool ended
+    pub fn finish(&mut self, status: ToolExitStatus, duration_ms: u64) {
+        let (exit_code, status) = match status {
+            ToolExitStatus::Exited(code) => (code, "exited"),
+            ToolExitStatus::TimedOut => (None, "timed_out"),
+            ToolExitStatus::Cancelled => (None, "cancelled"),
+        };
+        self.exit_code = exit_code;
+        self.status = status;
+        self.duration_ms = duration_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::encoder_factory::EncoderKind;
+    use crate::permissions::{Permission, PermissionState};
+
+    #[test]
+    fn test_service_report_schema() {
+        let mut status = ServiceStatus::new(ServiceState::Running);
+        status.since = Some(Local::now() - chrono::Duration::seconds(90));
+        status.pid = Some(4242);
+        let json = serde_json::to_value(ServiceReport::from(&status)).unwrap();
+
+        assert_eq!(json["state"], "running");
+        assert!(json.get("detail").is_none());
+        assert!(json["uptime_secs"].as_i64().unwrap() >= 90);
+        assert_eq!(json["pid"], 4242);
+        assert!(json["last_exit_code"].is_null());
+        assert!(json["since"].is_string());
+
+        let status = ServiceStatus::new(ServiceState::Unknown("activating".to_string()));
+        let json = serde_json::to_value(ServiceReport::from(&status)).unwrap();
+        assert_eq!(json["state"], "unknown");
+        assert_eq!(json["detail"], "activating");
+        assert!(json["uptime_secs"].is_null());
+    }
+
+    #[test]
+    fn test_tool_run_report_schema() {
+        let mut report = ToolRunReport::default();
+        report.push_stdout("hello");
+        report.push_stderr("warning");
+        report.finish(ToolExitStatus::Exited(Some(3)), 125);
+        let json = serde_json::to_value(&report).unwrap();
+
+        let mut keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["duration_ms", "exit_code", "status", "stderr", "stdout", "truncated"]);
+        assert_eq!(json["exit_code"], 3);
+        assert_eq!(json["stdout"], "hello\n");
+        assert_eq!(json["stderr"], "warning\n");
+        assert_eq!(json["duration_ms"], 125);
+        assert_eq!(json["status"], "exited");
+
+        report.finish(ToolExitStatus::TimedOut, 30_000);
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["exit_code"].is_null());
+        assert_eq!(json["status"], "timed_out");
+    }
+
+    #[test]
+    fn test_device_report_schema() {
+        let report = DeviceReport {
+            agent_id: None,
+            hostname: "host".to_string(),
+            os_info: serde_json::json!({ "platform": "linux" }),
+            encoder: Some(EncoderReport {
+                name: "x264".to_string(),
+                hardware_accelerated: false,
+                skipped: vec![SkippedEncoder { encoder: EncoderKind::NvencH264, reason: "no device".to_string() }],
+            }),
+            permissions: vec![PermissionStatus {
+                permission: Permission::ScreenRecording,
+                state: PermissionState::Missing,
+                remediation: Some(Permission::ScreenRecording.remediation()),
+            }],
+        };
+        let json = serde_json::to_value(&report).unwrap();
+
+        assert!(json["agent_id"].is_null());
+        assert_eq!(json["os_info"]["platform"], "linux");
+        assert_eq!(json["encoder"]["name"], "x264");
+        assert_eq!(json["encoder"]["skipped"][0]["encoder"], "nvenc-h264");
+        assert_eq!(json["encoder"]["skipped"][0]["reason"], "no device");
+        assert_eq!(json["permissions"][0]["permission"], "screen_recording");
+        assert_eq!(json["permissions"][0]["state"], "missing");
+        assert!(json["permissions"][0]["remediation"].as_str().unwrap().contains("System Settings"));
+    }
+}
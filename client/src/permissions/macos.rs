@@ -0,0 +1,80 @@
+//! Screen Recording and Accessibility checks through CoreGraphics and the
+//! Accessibility API
+
+use std::ffi::c_void;
+
+use super::{Permission, PermissionApi};
+
+type CFTypeRef = *const c_void;
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGPreflightScreenCaptureAccess() -> bool;
+    fn CGRequestScreenCaptureAccess() -> bool;
+}
+
+#[link(name = "ApplicationServices", kind = "framework")]
+extern "C" {
+    fn AXIsProcessTrusted() -> bool;
+    fn AXIsProcessTrustedWithOptions(options: CFTypeRef) -> bool;
+    static kAXTrustedCheckOptionPrompt: CFTypeRef;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFDictionaryCreate(
+        allocator: CFTypeRef,
+        keys: *const CFTypeRef,
+        values: *const CFTypeRef,
+        count: isize,
+        key_callbacks: *const c_void,
+        value_callbacks: *const c_void,
+    ) -> CFTypeRef;
+    fn CFRelease(object: CFTypeRef);
+    static kCFBooleanTrue: CFTypeRef;
+    static kCFTypeDictionaryKeyCallBacks: c_void;
+    static kCFTypeDictionaryValueCallBacks: c_void;
+}
+
+/// The TCC database, as this process sees it
+pub struct SystemPermissions;
+
+impl PermissionApi for SystemPermissions {
+    fn is_granted(&self, permission: Permission) -> bool {
+        unsafe {
+            match permission {
+                Permission::ScreenRecording => CGPreflightScreenCaptureAccess(),
+                Permission::Accessibility => AXIsProcessTrusted(),
+            }
+        }
+    }
+
+    fn request(&self, permission: Permission) {
+        unsafe {
+            match permission {
+                // Adds the agent to the Screen Recording list and shows the
+                // prompt, unless the user already answered it
+                Permission::ScreenRecording => {
+                    CGRequestScreenCaptureAccess();
+                }
+                Permission::Accessibility => {
+                    let keys = [kAXTrustedCheckOptionPrompt];
+                    let values = [kCFBooleanTrue];
+                    let options = CFDictionaryCreate(
+                        std::ptr::null(),
+                        keys.as_ptr(),
+                        values.as_ptr(),
+                        1,
+                        &kCFTypeDictionaryKeyCallBacks,
+                        &kCFTypeDictionaryValueCallBacks,
+                    );
+                    if options.is_null() {
+                        return;
+                    }
+                    AXIsProcessTrustedWithOptions(options);
+                    CFRelease(options);
+                }
+            }
+        }
+    }
+}
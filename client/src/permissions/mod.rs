@@ -0,0 +1,357 @@
+//! Privacy permissions the agent needs from macOS
+//!
+//! Without Screen Recording, CoreGraphics hands back black frames; without
+//! Accessibility, injected input is silently dropped. Neither fails, so
+//! the agent checks them itself: at startup, where a missing one brings up
+//! the system prompt, in each session's pre-flight, and every few seconds
+//! while it runs. The recheck picks up a grant made in System Settings and
+//! notices one taken away mid-session (`tccutil reset`, a new MDM profile),
+//! which ends the sessions relying on it. Other platforms ask for neither,
+//! and every check passes there.
+
+#[cfg(target_os = "macos")]
+pub mod macos;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::error::PreflightError;
+
+/// How often a running agent checks its permissions again
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A privacy permission the user grants in System Settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Needed to capture anything but the wallpaper
+    ScreenRecording,
+    /// Needed to inject keyboard and mouse events
+    Accessibility,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 2] = [Permission::ScreenRecording, Permission::Accessibility];
+
+    /// The permissions this platform makes the agent ask for
+    pub fn required() -> &'static [Permission] {
+        if cfg!(target_os = "macos") {
+            &Self::ALL
+        } else {
+            &[]
+        }
+    }
+
+    /// What the user has to do to grant it. macOS only hands a Screen
+    /// Recording grant to a process started after it.
+    pub fn remediation(self) -> String {
+        match self {
+            Permission::ScreenRecording => format!(
+                "grant {} in System Settings → Privacy & Security → {}, then restart the agent",
+                self, self
+            ),
+            Permission::Accessibility => format!(
+                "grant {} in System Settings → Privacy & Security → {}",
+                self, self
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Permission::ScreenRecording => "Screen Recording",
+            Permission::Accessibility => "Accessibility",
+        })
+    }
+}
+
+/// Where a permission stands, as of its last check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissionState {
+    Granted,
+    /// Not granted; the system prompt has been shown
+    Missing,
+    /// Granted earlier in this run and taken away since
+    Revoked,
+}
+
+impl std::fmt::Display for PermissionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PermissionState::Granted => "granted",
+            PermissionState::Missing => "missing",
+            PermissionState::Revoked => "revoked",
+        })
+    }
+}
+
+impl PermissionState {
+    /// The state after a check found the permission `granted` or not,
+    /// and whether the system prompt should be shown for it
+    fn next(previous: Option<PermissionState>, granted: bool) -> (PermissionState, bool) {
+        match (previous, granted) {
+            (_, true) => (PermissionState::Granted, false),
+            (None, false) => (PermissionState::Missing, true),
+            // A reset put it back to undecided, so the prompt shows again
+            (Some(PermissionState::Granted), false) => (PermissionState::Revoked, true),
+            // Asked already; the prompt would not show up again
+            (Some(state), false) => (state, false),
+        }
+    }
+}
+
+/// One permission for `info` and `doctor`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionStatus {
+    pub permission: Permission,
+    pub state: PermissionState,
+    /// What to do about it, unless it is granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remediation: Option<String>,
+}
+
+/// The system calls behind the checks; the platform's in the agent, mocks
+/// in tests
+pub trait PermissionApi: Send {
+    fn is_granted(&self, permission: Permission) -> bool;
+
+    /// Show the system prompt asking the user for `permission`
+    fn request(&self, permission: Permission);
+}
+
+impl<T: PermissionApi + ?Sized> PermissionApi for Box<T> {
+    fn is_granted(&self, permission: Permission) -> bool {
+        (**self).is_granted(permission)
+    }
+
+    fn request(&self, permission: Permission) {
+        (**self).request(permission)
+    }
+}
+
+/// Platforms without privacy permissions
+#[cfg(any(test, not(target_os = "macos")))]
+struct Unrestricted;
+
+#[cfg(any(test, not(target_os = "macos")))]
+impl PermissionApi for Unrestricted {
+    fn is_granted(&self, _permission: Permission) -> bool {
+        true
+    }
+
+    fn request(&self, _permission: Permission) {}
+}
+
+/// Tracks the required permissions from check to check
+pub struct PermissionMonitor<A> {
+    api: A,
+    required: Vec<Permission>,
+    states: HashMap<Permission, PermissionState>,
+}
+
+impl<A: PermissionApi> PermissionMonitor<A> {
+    pub fn new(api: A, required: &[Permission]) -> Self {
+        Self { api, required: required.to_vec(), states: HashMap::new() }
+    }
+
+    /// Check `permission`, prompting for it if it went missing
+    pub fn check(&mut self, permission: Permission) -> PermissionState {
+        let previous = self.states.get(&permission).copied();
+        let (state, prompt) = PermissionState::next(previous, self.api.is_granted(permission));
+        if prompt {
+            self.api.request(permission);
+        }
+        self.states.insert(permission, state);
+        state
+    }
+
+    /// Check every required permission; those whose state changed since
+    /// the last check come back
+    pub fn recheck(&mut self) -> Vec<(Permission, PermissionState)> {
+        let mut changed = Vec::new();
+        for permission in self.required.clone() {
+            let previous = self.states.get(&permission).copied();
+            let state = self.check(permission);
+            if previous != Some(state) {
+                changed.push((permission, state));
+            }
+        }
+        changed
+    }
+
+    /// Fail unless `permission` is granted or not needed here
+    pub fn require(&mut self, permission: Permission) -> Result<(), PreflightError> {
+        if !self.required.contains(&permission) || self.check(permission) == PermissionState::Granted {
+            return Ok(());
+        }
+        Err(PreflightError::PermissionMissing {
+            permission: permission.to_string(),
+            remediation: permission.remediation(),
+        })
+    }
+
+    /// Every required permission as of its last check
+    pub fn statuses(&self) -> Vec<PermissionStatus> {
+        self.required
+            .iter()
+            .filter_map(|&permission| {
+                let state = *self.states.get(&permission)?;
+                let remediation = (state != PermissionState::Granted).then(|| permission.remediation());
+                Some(PermissionStatus { permission, state, remediation })
+            })
+            .collect()
+    }
+}
+
+type SystemMonitor = PermissionMonitor<Box<dyn PermissionApi>>;
+
+static MONITOR: OnceLock<Mutex<SystemMonitor>> = OnceLock::new();
+
+fn monitor() -> std::sync::MutexGuard<'static, SystemMonitor> {
+    MONITOR
+        .get_or_init(|| {
+            #[cfg(target_os = "macos")]
+            let api: Box<dyn PermissionApi> = Box::new(macos::SystemPermissions);
+            #[cfg(not(target_os = "macos"))]
+            let api: Box<dyn PermissionApi> = Box::new(Unrestricted);
+            Mutex::new(PermissionMonitor::new(api, Permission::required()))
+        })
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Check the permissions this process holds, logging what changed;
+/// missing ones bring up the system prompt the first time
+pub fn recheck() -> Vec<(Permission, PermissionState)> {
+    let changed = monitor().recheck();
+    for (permission, state) in &changed {
+        match state {
+            PermissionState::Granted => info!("{} permission granted", permission),
+            PermissionState::Missing => warn!("{} permission missing: {}", permission, permission.remediation()),
+            PermissionState::Revoked => warn!("{} permission was revoked: {}", permission, permission.remediation()),
+        }
+    }
+    changed
+}
+
+/// Fail with what to do unless `permission` is granted
+pub fn require(permission: Permission) -> Result<(), PreflightError> {
+    monitor().require(permission)
+}
+
+/// Every required permission, checked now
+pub fn statuses() -> Vec<PermissionStatus> {
+    recheck();
+    monitor().statuses()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Grants flipped by the test, with the prompts shown
+    #[derive(Default, Clone)]
+    struct MockApi {
+        granted: Arc<Mutex<Vec<Permission>>>,
+        prompts: Arc<Mutex<Vec<Permission>>>,
+    }
+
+    impl MockApi {
+        fn grant(&self, permission: Permission) {
+            self.granted.lock().unwrap().push(permission);
+        }
+
+        fn revoke(&self, permission: Permission) {
+            self.granted.lock().unwrap().retain(|&p| p != permission);
+        }
+
+        fn prompts(&self) -> Vec<Permission> {
+            self.prompts.lock().unwrap().clone()
+        }
+    }
+
+    impl PermissionApi for MockApi {
+        fn is_granted(&self, permission: Permission) -> bool {
+            self.granted.lock().unwrap().contains(&permission)
+        }
+
+        fn request(&self, permission: Permission) {
+            self.prompts.lock().unwrap().push(permission);
+        }
+    }
+
+    #[test]
+    fn test_missing_permission_is_prompted_once_then_picked_up() {
+        let api = MockApi::default();
+        api.grant(Permission::Accessibility);
+        let mut monitor = PermissionMonitor::new(api.clone(), &Permission::ALL);
+
+        assert_eq!(monitor.recheck(), vec![
+            (Permission::ScreenRecording, PermissionState::Missing),
+            (Permission::Accessibility, PermissionState::Granted),
+        ]);
+        assert_eq!(api.prompts(), vec![Permission::ScreenRecording]);
+
+        // Still missing: nothing changed and the prompt is not shown again
+        assert!(monitor.recheck().is_empty());
+        assert!(monitor.require(Permission::ScreenRecording).is_err());
+        assert_eq!(api.prompts(), vec![Permission::ScreenRecording]);
+
+        // The user grants it in System Settings
+        api.grant(Permission::ScreenRecording);
+        assert_eq!(monitor.recheck(), vec![(Permission::ScreenRecording, PermissionState::Granted)]);
+        assert!(monitor.require(Permission::ScreenRecording).is_ok());
+    }
+
+    #[test]
+    fn test_reset_permission_is_revoked_and_asked_for_again() {
+        let api = MockApi::default();
+        api.grant(Permission::ScreenRecording);
+        api.grant(Permission::Accessibility);
+        let mut monitor = PermissionMonitor::new(api.clone(), &Permission::ALL);
+        monitor.recheck();
+
+        api.revoke(Permission::ScreenRecording);
+        assert_eq!(monitor.recheck(), vec![(Permission::ScreenRecording, PermissionState::Revoked)]);
+        assert_eq!(api.prompts(), vec![Permission::ScreenRecording]);
+        assert!(monitor.recheck().is_empty());
+
+        let statuses = monitor.statuses();
+        assert_eq!(statuses[0].state, PermissionState::Revoked);
+        assert!(statuses[0].remediation.as_deref().unwrap().contains("restart the agent"));
+        assert_eq!(statuses[1].remediation, None);
+
+        api.grant(Permission::ScreenRecording);
+        assert_eq!(monitor.recheck(), vec![(Permission::ScreenRecording, PermissionState::Granted)]);
+    }
+
+    #[test]
+    fn test_missing_permission_fails_with_remediation() {
+        let api = MockApi::default();
+        let mut monitor = PermissionMonitor::new(api.clone(), &[Permission::Accessibility]);
+
+        let err = monitor.require(Permission::Accessibility).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Accessibility permission missing: grant Accessibility in System Settings → Privacy & Security → Accessibility"
+        );
+        assert_eq!(err.code(), Some(crate::session::preflight::FailureCode::PermissionMissing));
+        // Not required here, so never checked
+        assert!(monitor.require(Permission::ScreenRecording).is_ok());
+        assert_eq!(api.prompts(), vec![Permission::Accessibility]);
+    }
+
+    #[test]
+    fn test_nothing_is_required_off_macos() {
+        let mut monitor = PermissionMonitor::new(Unrestricted, Permission::required());
+        assert!(monitor.recheck().iter().all(|(_, state)| *state == PermissionState::Granted));
+        assert!(monitor.require(Permission::ScreenRecording).is_ok());
+    }
+}
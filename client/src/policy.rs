@@ -0,0 +1,354 @@
+//! Server-managed policy
+//!
+//! The relay sends a `PolicyUpdate` with the agent's effective policy when
+//! it registers and whenever an admin changes a policy that applies to it.
+//! Each setting the policy makes replaces the one in the local config; the
+//! rest stay as configured. The agent keeps the last policy in
+//! `policy.json` next to its config, so it holds until the server is
+//! reachable again.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::ClientConfig;
+use crate::session::SessionType;
+use crate::toolbox::policy::ToolPolicy;
+use crate::toolbox::ToolboxConfig;
+
+/// Settings pushed by the server; unset ones leave the local config alone
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fps: Option<u32>,
+    /// Bandwidth ceiling no session's cap may exceed, in kbit/s
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth_kbps: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_interval_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clipboard_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_transfer_enabled: Option<bool>,
+    /// Whether the technician may drop files onto the screen; the relay
+    /// enforces the size limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_drop_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toolbox_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_required: Option<bool>,
+    /// Session types the agent accepts; unset accepts all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_session_types: Option<Vec<String>>,
+    /// SHA-256 of tool executables that may run; unset allows any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_tool_checksums: Option<Vec<String>>,
+    /// Tool categories that may run; unset allows any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_tool_categories: Option<Vec<String>>,
+    /// Whether sessions need the end user's consent; the server decides
+    /// per session and says so in the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_required: Option<bool>,
+    /// Seconds the consent prompt waits for an answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_timeout_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_timeout_action: Option<ConsentTimeoutAction>,
+    /// Whether the agent reports its hardware and software inventory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inventory_enabled: Option<bool>,
+    /// Show the remote input indicator during every session, backstage
+    /// ones included, whatever the local config says
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_indicator_required: Option<bool>,
+    /// Whether the agent uploads its crash reports
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crash_reports_enabled: Option<bool>,
+    /// Share of one core capture and encoding may use, in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_budget_percent: Option<u32>,
+    /// Memory frame and recording buffers may hold, in MB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<u64>,
+    /// Run below normal CPU and I/O priority
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_priority: Option<bool>,
+    /// Which sessions the end user may end from the tray
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_disconnect: Option<UserDisconnect>,
+    /// Whether the end user may pause remote access
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_pause_enabled: Option<bool>,
+    /// Longest pause the end user may start, in hours
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_user_pause_hours: Option<u32>,
+}
+
+/// What an unanswered consent prompt means
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentTimeoutAction {
+    Accept,
+    #[default]
+    Decline,
+}
+
+/// Which sessions the end user may end
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserDisconnect {
+    #[default]
+    All,
+    /// All but backstage sessions, which the end user does not see
+    Attended,
+    None,
+}
+
+/// How long the consent prompt waits when the policy sets no limit
+pub const DEFAULT_CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest pause the end user may start when the policy sets no limit, in hours
+pub const DEFAULT_MAX_USER_PAUSE_HOURS: u32 = 24;
+
+impl ServerPolicy {
+    /// Overwrite the settings of `config` this policy makes
+    pub fn apply(&self, config: &mut ClientConfig) {
+        if let Some(max_fps) = self.max_fps {
+            let adaptive = &mut config.encoding.adaptive;
+            adaptive.max_fps = max_fps.max(1);
+            adaptive.min_fps = adaptive.min_fps.min(adaptive.max_fps);
+        }
+        if let Some(kbps) = self.max_bandwidth_kbps {
+            config.encoding.max_bandwidth_kbps = Some(kbps);
+        }
+        if let Some(secs) = self.heartbeat_interval_secs {
+            config.heartbeat_interval = secs;
+        }
+        if let Some(enabled) = self.clipboard_enabled {
+            config.clipboard.enabled = enabled;
+        }
+        if let Some(enabled) = self.file_transfer_enabled {
+            config.file_transfer.enabled = enabled;
+        }
+        if let Some(enabled) = self.file_drop_enabled {
+            config.file_transfer.drops_enabled = enabled;
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            config.idle.timeout_secs = secs;
+            config.idle.backstage_timeout_secs = secs;
+            config.adhoc.idle_timeout_secs = secs;
+        }
+        if self.recording_required == Some(true) {
+            config.recording.enabled = true;
+        }
+        if let Some(enabled) = self.inventory_enabled {
+            config.inventory.enabled = enabled;
+        }
+        if self.input_indicator_required == Some(true) {
+            config.input_indicator.require();
+        }
+        if let Some(enabled) = self.crash_reports_enabled {
+            config.crash_reports.upload = enabled;
+        }
+        if let Some(percent) = self.cpu_budget_percent {
+            config.resources.cpu_budget_percent = Some(percent);
+        }
+        if let Some(mb) = self.memory_limit_mb {
+            config.resources.memory_limit_mb = Some(mb);
+        }
+        if let Some(background) = self.background_priority {
+            config.resources.background_priority = background;
+        }
+    }
+
+    /// Overwrite the toolbox settings this policy makes
+    pub fn apply_toolbox(&self, config: &mut ToolboxConfig) {
+        if let Some(enabled) = self.toolbox_enabled {
+            config.enabled = enabled;
+        }
+        config.policy = ToolPolicy {
+            allowed_checksums: self.allowed_tool_checksums.clone(),
+            allowed_categories: self.allowed_tool_categories.clone(),
+        };
+    }
+
+    /// Whether sessions of `session_type` may be started
+    pub fn allows_session(&self, session_type: SessionType) -> bool {
+        let name = session_type.to_string();
+        self.allowed_session_types
+            .as_ref()
+            .is_none_or(|types| types.iter().any(|allowed| allowed.eq_ignore_ascii_case(&name)))
+    }
+
+    /// Whether every session must be recorded, whatever the request asks for
+    pub fn recording_required(&self) -> bool {
+        self.recording_required == Some(true)
+    }
+
+    /// How long the consent prompt waits for an answer
+    pub fn consent_timeout(&self) -> Duration {
+        self.consent_timeout_secs.map_or(DEFAULT_CONSENT_TIMEOUT, Duration::from_secs)
+    }
+
+    /// What happens when nobody answers the consent prompt, or there is no
+    /// display to show it on
+    pub fn consent_timeout_action(&self) -> ConsentTimeoutAction {
+        self.consent_timeout_action.unwrap_or_default()
+    }
+
+    /// Whether the end user may end a session of `session_type`
+    pub fn allows_user_disconnect(&self, session_type: SessionType) -> bool {
+        match self.user_disconnect.unwrap_or_default() {
+            UserDisconnect::All => true,
+            UserDisconnect::Attended => session_type != SessionType::Backstage,
+            UserDisconnect::None => false,
+        }
+    }
+
+    /// Longest pause the end user may start, in hours; none when they may
+    /// not pause
+    pub fn user_pause_limit(&self) -> Option<u32> {
+        (self.user_pause_enabled != Some(false)).then(|| self.max_user_pause_hours.unwrap_or(DEFAULT_MAX_USER_PAUSE_HOURS))
+    }
+}
+
+/// Where the last policy from the server is kept
+#[derive(Debug, Clone)]
+pub struct PolicyStore {
+    path: PathBuf,
+}
+
+impl PolicyStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// `policy.json` next to the default config
+    pub fn default_location() -> Self {
+        Self::new(ClientConfig::default_path().with_file_name("policy.json"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored policy; a missing or unreadable file counts as none
+    pub fn load(&self) -> Option<ServerPolicy> {
+        let content = std::fs::read_to_string(&self.path).ok()?;
+        match serde_json::from_str(&content) {
+            Ok(policy) => Some(policy),
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable policy {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, policy: &ServerPolicy) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(policy)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ClientConfig {
+        ClientConfig::new("wss://relay.example.com".to_string(), Some("Test Device".to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_policy_wins_over_local_config() {
+        let mut config = config();
+        config.clipboard.enabled = true;
+        config.recording.enabled = false;
+        let local = config.clone();
+
+        let policy: ServerPolicy = serde_json::from_str(
+            r#"{"max_fps": 3, "max_bandwidth_kbps": 1500, "clipboard_enabled": false, "file_drop_enabled": false, "idle_timeout_secs": 600, "recording_required": true, "allowed_session_types": ["console"], "inventory_enabled": false, "input_indicator_required": true, "crash_reports_enabled": true, "cpu_budget_percent": 40, "background_priority": true}"#,
+        ).unwrap();
+        policy.apply(&mut config);
+        assert_eq!(config.encoding.adaptive.max_fps, 3);
+        assert_eq!(config.encoding.adaptive.min_fps, 3);
+        assert_eq!(config.encoding.max_bandwidth_kbps, Some(1500));
+        assert!(!config.clipboard.enabled);
+        assert!(!config.file_transfer.drops_enabled);
+        assert!(config.recording.enabled);
+        assert!(!config.inventory.enabled);
+        assert!(config.input_indicator.backstage);
+        assert!(config.crash_reports.upload);
+        assert_eq!(config.resources.cpu_budget_percent, Some(40));
+        assert!(config.resources.background_priority);
+        assert_eq!(config.idle.backstage_timeout_secs, 600);
+        assert_eq!(config.adhoc.idle_timeout_secs, 600);
+
+        // What the policy leaves unset stays as configured
+        assert_eq!(config.heartbeat_interval, local.heartbeat_interval);
+        assert_eq!(config.file_transfer.enabled, local.file_transfer.enabled);
+        assert_eq!(config.resources.memory_limit_mb, local.resources.memory_limit_mb);
+
+        assert!(policy.allows_session(SessionType::Console));
+        assert!(!policy.allows_session(SessionType::Backstage));
+        assert!(ServerPolicy::default().allows_session(SessionType::AdHoc));
+
+        let mut toolbox = ToolboxConfig::default();
+        let policy: ServerPolicy = serde_json::from_str(r#"{"allowed_tool_categories": ["network"]}"#).unwrap();
+        policy.apply_toolbox(&mut toolbox);
+        assert!(toolbox.enabled);
+        assert_eq!(toolbox.policy.allowed_categories, Some(vec!["network".to_string()]));
+    }
+
+    #[test]
+    fn test_consent_defaults() {
+        let policy = ServerPolicy::default();
+        assert_eq!(policy.consent_timeout(), DEFAULT_CONSENT_TIMEOUT);
+        assert_eq!(policy.consent_timeout_action(), ConsentTimeoutAction::Decline);
+
+        let policy: ServerPolicy = serde_json::from_str(
+            r#"{"consent_required": true, "consent_timeout_secs": 60, "consent_timeout_action": "accept", "backstage_consent_bypass": false}"#,
+        ).unwrap();
+        assert_eq!(policy.consent_timeout(), Duration::from_secs(60));
+        assert_eq!(policy.consent_timeout_action(), ConsentTimeoutAction::Accept);
+    }
+
+    #[test]
+    fn test_user_disconnect_and_pause_gates() {
+        let policy = ServerPolicy::default();
+        assert!(policy.allows_user_disconnect(SessionType::Backstage));
+        assert_eq!(policy.user_pause_limit(), Some(DEFAULT_MAX_USER_PAUSE_HOURS));
+
+        let policy: ServerPolicy = serde_json::from_str(r#"{"user_disconnect": "attended", "max_user_pause_hours": 4}"#).unwrap();
+        assert!(policy.allows_user_disconnect(SessionType::Console));
+        assert!(policy.allows_user_disconnect(SessionType::AdHoc));
+        assert!(!policy.allows_user_disconnect(SessionType::Backstage));
+        assert_eq!(policy.user_pause_limit(), Some(4));
+
+        let policy: ServerPolicy = serde_json::from_str(r#"{"user_disconnect": "none", "user_pause_enabled": false, "max_user_pause_hours": 4}"#).unwrap();
+        assert!(!policy.allows_user_disconnect(SessionType::AdHoc));
+        assert_eq!(policy.user_pause_limit(), None);
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PolicyStore::new(dir.path().join("ghostlink").join("policy.json"));
+        assert_eq!(store.load(), None);
+
+        let policy = ServerPolicy { heartbeat_interval_secs: Some(10), toolbox_enabled: Some(false), ..Default::default() };
+        store.save(&policy).unwrap();
+        assert_eq!(store.load(), Some(policy));
+
+        std::fs::write(store.path(), "not json").unwrap();
+        assert_eq!(store.load(), None);
+    }
+}
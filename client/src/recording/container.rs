@@ -0,0 +1,411 @@
+//! On-disk recording container
+//!
+//! A recording is a sequence of segment files plus a JSON sidecar (see
+//! `session_recorder`). Each segment starts with a magic number followed by
+//! length-prefixed records:
+//!
+//! ```text
+//! segment: "GLR1" | record*
+//! record:  length (u32 LE, of everything after it) | kind (u8) | timestamp µs (u64 LE) | payload
+//! frame:   width (u32 LE) | height (u32 LE) | flags (u8, bit 0 = keyframe) | encoded data
+//! event:   JSON `TimelineEntry`
+//! ```
+//!
+//! A segment cut short by a crash ends at its last complete record.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::session_recorder::SessionEventType;
+
+const SEGMENT_MAGIC: &[u8; 4] = b"GLR1";
+
+/// Extension of segment files
+pub const SEGMENT_EXTENSION: &str = "glr";
+
+const KIND_FRAME: u8 = 1;
+const KIND_EVENT: u8 = 2;
+
+const FLAG_KEYFRAME: u8 = 1;
+
+/// Bytes before the payload: length, kind and timestamp
+const RECORD_HEADER_LEN: u64 = 4 + 1 + 8;
+
+/// Larger records are treated as corruption rather than allocated
+const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+
+/// An encoded video frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameRecord {
+    pub timestamp_us: u64,
+    pub width: u32,
+    pub height: u32,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// Session activity on the recording timeline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEntry {
+    Session {
+        event: SessionEventType,
+        description: String,
+    },
+    Input {
+        event_type: String,
+        data: serde_json::Value,
+    },
+    Command {
+        command: String,
+        exit_code: Option<i32>,
+    },
+    Chat {
+        sender: String,
+        message: String,
+    },
+    System {
+        event_type: String,
+        #[serde(default)]
+        details: HashMap<String, String>,
+    },
+}
+
+/// One record of a segment
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Frame(FrameRecord),
+    Event {
+        timestamp_us: u64,
+        entry: TimelineEntry,
+    },
+}
+
+impl Record {
+    pub fn timestamp_us(&self) -> u64 {
+        match self {
+            Record::Frame(frame) => frame.timestamp_us,
+            Record::Event { timestamp_us, .. } => *timestamp_us,
+        }
+    }
+
+    fn encode(&self) -> io::Result<(u8, Vec<u8>)> {
+        match self {
+            Record::Frame(frame) => {
+                let mut payload = Vec::with_capacity(9 + frame.data.len());
+                payload.extend_from_slice(&frame.width.to_le_bytes());
+                payload.extend_from_slice(&frame.height.to_le_bytes());
+                payload.push(if frame.keyframe { FLAG_KEYFRAME } else { 0 });
+                payload.extend_from_slice(&frame.data);
+                Ok((KIND_FRAME, payload))
+            }
+            Record::Event { entry, .. } => Ok((KIND_EVENT, serde_json::to_vec(entry)?)),
+        }
+    }
+
+    fn decode(kind: u8, timestamp_us: u64, payload: Vec<u8>) -> io::Result<Self> {
+        match kind {
+            KIND_FRAME if payload.len() >= 9 => Ok(Record::Frame(FrameRecord {
+                timestamp_us,
+                width: u32::from_le_bytes(payload[0..4].try_into().unwrap()),
+                height: u32::from_le_bytes(payload[4..8].try_into().unwrap()),
+                keyframe: payload[8] & FLAG_KEYFRAME != 0,
+                data: payload[9..].to_vec(),
+            })),
+            KIND_EVENT => Ok(Record::Event {
+                timestamp_us,
+                entry: serde_json::from_slice(&payload)?,
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid record of kind {}", kind))),
+        }
+    }
+}
+
+/// Name of segment `index` of recording `recording_id`
+pub fn segment_file_name(recording_id: &str, index: usize) -> String {
+    format!("{}.{:04}.{}", recording_id, index, SEGMENT_EXTENSION)
+}
+
+/// Index entry for one segment file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SegmentInfo {
+    /// File name, relative to the recording directory
+    pub file_name: String,
+    pub bytes: u64,
+    pub frames: u64,
+    pub events: u64,
+    pub first_timestamp_us: Option<u64>,
+    pub last_timestamp_us: Option<u64>,
+}
+
+/// Appends records to segment files, starting a new one at `segment_size` bytes
+pub struct RecordingWriter {
+    directory: PathBuf,
+    recording_id: String,
+    segment_size: u64,
+    file: BufWriter<File>,
+    segments: Vec<SegmentInfo>,
+}
+
+impl RecordingWriter {
+    /// Start the first segment of recording `recording_id` in `directory`
+    pub fn create(directory: &Path, recording_id: &str, segment_size: u64) -> io::Result<Self> {
+        let (file, info) = Self::open_segment(directory, recording_id, 0)?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            recording_id: recording_id.to_string(),
+            segment_size: segment_size.max(1),
+            file,
+            segments: vec![info],
+        })
+    }
+
+    fn open_segment(directory: &Path, recording_id: &str, index: usize) -> io::Result<(BufWriter<File>, SegmentInfo)> {
+        let file_name = segment_file_name(recording_id, index);
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(directory.join(&file_name))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(SEGMENT_MAGIC)?;
+
+        let info = SegmentInfo {
+            file_name,
+            bytes: SEGMENT_MAGIC.len() as u64,
+            ..Default::default()
+        };
+        Ok((file, info))
+    }
+
+    /// Append a record, rotating first if it would overflow a non-empty segment.
+    ///
+    /// Returns true when a new segment was started.
+    pub fn append(&mut self, record: &Record) -> io::Result<bool> {
+        let (kind, payload) = record.encode()?;
+        if payload.len() as u64 + RECORD_HEADER_LEN > MAX_RECORD_LEN as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Record too large"));
+        }
+        let record_len = RECORD_HEADER_LEN + payload.len() as u64;
+
+        let current = self.segments.last().expect("writer always has a segment");
+        let has_records = current.frames + current.events > 0;
+        let rotated = has_records && current.bytes + record_len > self.segment_size;
+        if rotated {
+            self.file.flush()?;
+            let (file, info) = Self::open_segment(&self.directory, &self.recording_id, self.segments.len())?;
+            self.file = file;
+            self.segments.push(info);
+        }
+
+        let timestamp_us = record.timestamp_us();
+        self.file.write_all(&((payload.len() as u32) + 9).to_le_bytes())?;
+        self.file.write_all(&[kind])?;
+        self.file.write_all(&timestamp_us.to_le_bytes())?;
+        self.file.write_all(&payload)?;
+
+        let segment = self.segments.last_mut().expect("writer always has a segment");
+        segment.bytes += record_len;
+        match record {
+            Record::Frame(_) => segment.frames += 1,
+            Record::Event { .. } => segment.events += 1,
+        }
+        segment.first_timestamp_us.get_or_insert(timestamp_us);
+        segment.last_timestamp_us = Some(timestamp_us);
+        Ok(rotated)
+    }
+
+    /// Push buffered records to the OS
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    pub fn segments(&self) -> &[SegmentInfo] {
+        &self.segments
+    }
+
+    /// Bytes written across all segments
+    pub fn total_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.bytes).sum()
+    }
+}
+
+/// Reads the records of one segment file
+pub struct SegmentReader {
+    file: BufReader<File>,
+}
+
+impl SegmentReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != SEGMENT_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a recording segment", path.display())));
+        }
+        Ok(Self { file })
+    }
+
+    /// Next record, or `None` at the end of the segment or a truncated tail
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut len = [0u8; 4];
+        if !read_or_eof(&mut self.file, &mut len)? {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(len);
+        if !(9..=MAX_RECORD_LEN).contains(&len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid record length {}", len)));
+        }
+
+        let mut body = vec![0u8; len as usize];
+        if !read_or_eof(&mut self.file, &mut body)? {
+            return Ok(None);
+        }
+        let kind = body[0];
+        let timestamp_us = u64::from_le_bytes(body[1..9].try_into().unwrap());
+        body.drain(..9);
+        Record::decode(kind, timestamp_us, body).map(Some)
+    }
+}
+
+/// Fill `buf`, returning false if the file ends first
+fn read_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the records of a whole recording, segment by segment
+pub struct RecordingReader {
+    directory: PathBuf,
+    pending: VecDeque<String>,
+    current: Option<SegmentReader>,
+}
+
+impl RecordingReader {
+    pub fn new(directory: &Path, segments: &[SegmentInfo]) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            pending: segments.iter().map(|segment| segment.file_name.clone()).collect(),
+            current: None,
+        }
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let file_name = self.pending.pop_front()?;
+                match SegmentReader::open(&self.directory.join(file_name)) {
+                    Ok(reader) => self.current = Some(reader),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            match self.current.as_mut()?.next_record() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => self.current = None,
+                Err(e) => {
+                    self.current = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn frame(timestamp_us: u64, size: usize) -> Record {
+        Record::Frame(FrameRecord {
+            timestamp_us,
+            width: 1920,
+            height: 1080,
+            keyframe: timestamp_us == 0,
+            data: vec![timestamp_us as u8; size],
+        })
+    }
+
+    fn event(timestamp_us: u64) -> Record {
+        Record::Event {
+            timestamp_us,
+            entry: TimelineEntry::Command {
+                command: "ipconfig /all".to_string(),
+                exit_code: Some(0),
+            },
+        }
+    }
+
+    #[test]
+    fn test_writer_reader_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let records = vec![frame(0, 500), event(10), frame(20, 300)];
+
+        let mut writer = RecordingWriter::create(dir.path(), "rec", 1 << 20).unwrap();
+        for record in &records {
+            assert!(!writer.append(record).unwrap());
+        }
+        writer.flush().unwrap();
+
+        let segments = writer.segments().to_vec();
+        assert_eq!(segments.len(), 1);
+        assert_eq!((segments[0].frames, segments[0].events), (2, 1));
+        assert_eq!((segments[0].first_timestamp_us, segments[0].last_timestamp_us), (Some(0), Some(20)));
+        assert_eq!(std::fs::metadata(dir.path().join(&segments[0].file_name)).unwrap().len(), segments[0].bytes);
+
+        let read: Vec<Record> = RecordingReader::new(dir.path(), &segments).collect::<io::Result<_>>().unwrap();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn test_rotation_starts_new_segments() {
+        let dir = TempDir::new().unwrap();
+        let mut writer = RecordingWriter::create(dir.path(), "rec", 1000).unwrap();
+
+        // Each frame record is 13 + 9 + 400 bytes, so two fit per segment
+        let rotations: Vec<bool> = (0..5).map(|i| writer.append(&frame(i, 400)).unwrap()).collect();
+        assert_eq!(rotations, vec![false, false, true, false, true]);
+        // A record larger than a segment still gets written, alone
+        assert!(writer.append(&frame(5, 2000)).unwrap());
+        writer.flush().unwrap();
+
+        let segments = writer.segments().to_vec();
+        assert_eq!(segments.iter().map(|s| s.frames).collect::<Vec<_>>(), vec![2, 2, 1, 1]);
+        assert_eq!(segments[1].file_name, "rec.0001.glr");
+        assert_eq!(writer.total_bytes(), segments.iter().map(|s| s.bytes).sum::<u64>());
+
+        let timestamps: Vec<u64> = RecordingReader::new(dir.path(), &segments)
+            .map(|record| record.unwrap().timestamp_us())
+            .collect();
+        assert_eq!(timestamps, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_truncated_segment_ends_at_last_complete_record() {
+        let dir = TempDir::new().unwrap();
+        let mut writer = RecordingWriter::create(dir.path(), "rec", 1 << 20).unwrap();
+        writer.append(&frame(0, 100)).unwrap();
+        writer.append(&event(1)).unwrap();
+        writer.flush().unwrap();
+        let segments = writer.segments().to_vec();
+        drop(writer);
+
+        // Simulate a crash in the middle of the second record
+        let path = dir.path().join(&segments[0].file_name);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 5).unwrap();
+
+        let read: Vec<Record> = RecordingReader::new(dir.path(), &segments).collect::<io::Result<_>>().unwrap();
+        assert_eq!(read, vec![frame(0, 100)]);
+    }
+}
@@ -0,0 +1,509 @@
+//! Video export of session recordings
+//!
+//! H.264 and HEVC recordings are remuxed into MP4 without touching the
+//! frames. Recordings made with the software JPEG/PNG encoder, tile frames
+//! included, and exports with a burned-in caption, are decoded and
+//! re-encoded to H.264; that path
+//! needs the `x264-encoder` feature. A recording cut short by a crash exports
+//! up to its last complete frame.
+
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use super::container::{FrameRecord, Record, RecordingReader};
+use crate::capture::tiles::TILE_MAGIC;
+use super::mp4::{split_annex_b, Mp4Codec, Mp4Summary, Mp4Writer};
+use super::session_recorder::{OperatorInfo, RecordingMetadata};
+use super::store::{find_recording, segment_files};
+
+/// Container to export to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    Mp4,
+    WebM,
+}
+
+impl VideoFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            VideoFormat::Mp4 => "mp4",
+            VideoFormat::WebM => "webm",
+        }
+    }
+}
+
+impl std::str::FromStr for VideoFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(VideoFormat::Mp4),
+            "webm" => Ok(VideoFormat::WebM),
+            other => Err(anyhow::anyhow!("Unknown video format: {}", other)),
+        }
+    }
+}
+
+/// How recorded frames are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameCodec {
+    H264,
+    Hevc,
+    Jpeg,
+    Png,
+    /// Tile frames of the software encoder, painted over the previous picture
+    Tiles,
+}
+
+impl FrameCodec {
+    /// Identify the encoder from a keyframe's bytes
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&TILE_MAGIC) {
+            return Some(FrameCodec::Tiles);
+        }
+        if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            return Some(FrameCodec::Jpeg);
+        }
+        if data.starts_with(b"\x89PNG") {
+            return Some(FrameCodec::Png);
+        }
+        split_annex_b(data).iter().find_map(|nal| {
+            // H.264 SPS, or a base-layer HEVC VPS/SPS/PPS (layer 0, temporal ID 0)
+            if nal[0] & 0x9f == 0x07 {
+                Some(FrameCodec::H264)
+            } else if nal.len() >= 2 && nal[0] & 0x81 == 0 && nal[1] == 0x01 && (32..=34).contains(&(nal[0] >> 1)) {
+                Some(FrameCodec::Hevc)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn mp4_codec(self) -> Option<Mp4Codec> {
+        match self {
+            FrameCodec::H264 => Some(Mp4Codec::H264),
+            FrameCodec::Hevc => Some(Mp4Codec::Hevc),
+            FrameCodec::Jpeg | FrameCodec::Png | FrameCodec::Tiles => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FrameCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameCodec::H264 => write!(f, "H.264"),
+            FrameCodec::Hevc => write!(f, "HEVC"),
+            FrameCodec::Jpeg => write!(f, "JPEG"),
+            FrameCodec::Png => write!(f, "PNG"),
+            FrameCodec::Tiles => write!(f, "tile"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VideoExportOptions {
+    pub format: VideoFormat,
+    /// Burn the time and technician name into every frame
+    pub caption: bool,
+}
+
+/// What an export produced
+#[derive(Debug, Clone)]
+pub struct VideoExportSummary {
+    pub output: PathBuf,
+    pub codec: FrameCodec,
+    pub frames: u64,
+    pub duration_us: u64,
+    /// Frames were re-encoded rather than copied
+    pub transcoded: bool,
+    /// The recording was finalized; otherwise it ended in a crash
+    pub complete: bool,
+}
+
+/// Export a recording, found by recording or session ID, as a playable video.
+///
+/// Writes to `output`, or `<recording_id>.<format>` in the current directory.
+pub async fn export_video(
+    directory: &Path,
+    id: &str,
+    output: Option<&Path>,
+    options: VideoExportOptions,
+) -> Result<VideoExportSummary> {
+    let metadata = find_recording(directory, id)?;
+    let codec = recorded_frames(directory, &metadata)
+        .filter(|frame| frame.keyframe)
+        .find_map(|frame| FrameCodec::detect(&frame.data))
+        .with_context(|| format!("Recording {} has no recognizable video frames", metadata.recording_id))?;
+
+    // Nothing in the capture pipeline produces VP8/VP9
+    if options.format == VideoFormat::WebM {
+        bail!("WebM export needs a VP8/VP9 recording, but {} was recorded as {}; export it as mp4", metadata.recording_id, codec);
+    }
+
+    let complete = metadata.end_time.is_some();
+    if !complete {
+        warn!("Recording {} was never finalized; exporting up to its last complete frame", metadata.recording_id);
+    }
+
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from(format!("{}.{}", metadata.recording_id, options.format.extension())));
+    let file = BufWriter::new(File::create(&output)
+        .with_context(|| format!("Failed to create {}", output.display()))?);
+
+    let frames = recorded_frames(directory, &metadata);
+    let transcoded = options.caption || codec.mp4_codec().is_none();
+    let result = match codec.mp4_codec() {
+        Some(mp4_codec) if !transcoded => remux_frames(frames, mp4_codec, file),
+        _ => transcode_frames(frames, codec, options.caption.then_some(&metadata.operator), file).await,
+    };
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = fs::remove_file(&output);
+            return Err(e);
+        }
+    };
+
+    info!("Exported {} ({} frames, {:.1}s) to {}",
+        metadata.recording_id,
+        summary.frames,
+        summary.duration_us as f64 / 1_000_000.0,
+        output.display());
+
+    Ok(VideoExportSummary {
+        output,
+        codec,
+        frames: summary.frames,
+        duration_us: summary.duration_us,
+        transcoded,
+        complete,
+    })
+}
+
+/// Frames of a recording in order, ending at the first damaged record
+fn recorded_frames(directory: &Path, metadata: &RecordingMetadata) -> impl Iterator<Item = FrameRecord> {
+    let recording_id = metadata.recording_id.clone();
+    RecordingReader::new(directory, &segment_files(directory, metadata))
+        .map_while(move |record| match record {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("Recording {} is damaged, exporting up to the damage: {}", recording_id, e);
+                None
+            }
+        })
+        .filter_map(|record| match record {
+            Record::Frame(frame) => Some(frame),
+            Record::Event { .. } => None,
+        })
+}
+
+/// Copy H.264/HEVC frames into an MP4 as recorded
+fn remux_frames(
+    frames: impl Iterator<Item = FrameRecord>,
+    codec: Mp4Codec,
+    out: BufWriter<File>,
+) -> Result<Mp4Summary> {
+    let mut writer: Option<Mp4Writer<BufWriter<File>>> = None;
+    let mut out = Some(out);
+    for frame in frames {
+        let writer = match writer.as_mut() {
+            Some(writer) => writer,
+            // Size the track after the first keyframe; earlier frames are skipped
+            None if frame.keyframe => writer.insert(Mp4Writer::new(out.take().unwrap(), codec, frame.width, frame.height)?),
+            None => continue,
+        };
+        writer.write_sample(frame.timestamp_us, &frame.data)?;
+    }
+
+    let writer = writer.context("Recording has no keyframe to start the video from")?;
+    Ok(writer.finish()?.1)
+}
+
+/// Caption burned into each frame
+fn caption_text(timestamp_us: u64, operator: &OperatorInfo) -> String {
+    let time = chrono::DateTime::from_timestamp((timestamp_us / 1_000_000) as i64, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default();
+    format!("{}  {}", time, operator.display_name)
+}
+
+#[cfg(not(feature = "x264-encoder"))]
+async fn transcode_frames(
+    _frames: impl Iterator<Item = FrameRecord>,
+    codec: FrameCodec,
+    caption: Option<&OperatorInfo>,
+    _out: BufWriter<File>,
+) -> Result<Mp4Summary> {
+    if caption.is_some() {
+        bail!("Burning in captions requires a build with the x264-encoder feature");
+    }
+    bail!("Exporting {} recordings requires a build with the x264-encoder feature", codec)
+}
+
+/// Decode, optionally caption, and re-encode frames to H.264
+#[cfg(feature = "x264-encoder")]
+async fn transcode_frames(
+    frames: impl Iterator<Item = FrameRecord>,
+    codec: FrameCodec,
+    caption: Option<&OperatorInfo>,
+    out: BufWriter<File>,
+) -> Result<Mp4Summary> {
+    use crate::capture::h264_encoder::H264Encoder;
+    use crate::capture::{Frame, PixelFormat, VideoEncoder};
+
+    /// Nominal rate for rate control; sample timing still comes from the recording
+    const EXPORT_FPS: u32 = 30;
+
+    let mut decoder = decode::FrameDecoder::new(codec)?;
+    let mut encoder: Option<(H264Encoder, u32, u32)> = None;
+    let mut writer: Option<Mp4Writer<BufWriter<File>>> = None;
+    let mut out = Some(out);
+
+    for record in frames {
+        let Some(mut rgba) = decoder.decode(&record.data)? else { continue };
+        let (width, height) = rgba.dimensions();
+        if let Some(operator) = caption {
+            super::overlay::draw_caption(&mut rgba, width, height, &caption_text(record.timestamp_us, operator));
+        }
+
+        // A display switch changed the frame size
+        if encoder.as_ref().map(|(_, w, h)| (*w, *h)) != Some((width, height)) {
+            let mut h264 = H264Encoder::new();
+            h264.initialize(width, height, EXPORT_FPS).await?;
+            encoder = Some((h264, width, height));
+        }
+        let (h264, _, _) = encoder.as_mut().expect("encoder initialized above");
+        // Keep keyframes where the recording had them, so seeking lands alike
+        if record.keyframe {
+            h264.force_keyframe();
+        }
+
+        let frame = Frame {
+            data: rgba.into_raw(),
+            width,
+            height,
+            pixel_format: PixelFormat::RGBA,
+            stride: width * 4,
+            timestamp: record.timestamp_us,
+        };
+        let encoded = h264.encode_frame(&frame).await?;
+        if encoded.is_empty() {
+            continue;
+        }
+
+        let writer = match writer.as_mut() {
+            Some(writer) => writer,
+            None => writer.insert(Mp4Writer::new(out.take().unwrap(), Mp4Codec::H264, width, height)?),
+        };
+        writer.write_sample(record.timestamp_us, &encoded)?;
+    }
+
+    let writer = writer.context("No frames could be transcoded")?;
+    Ok(writer.finish()?.1)
+}
+
+#[cfg(feature = "x264-encoder")]
+pub(crate) mod decode {
+    use anyhow::{Context, Result};
+    use ffmpeg_next as ffmpeg;
+    use image::RgbaImage;
+
+    use super::FrameCodec;
+    use crate::capture::tiles::TileFrame;
+
+    /// Turns recorded frames back into RGBA pictures
+    pub enum FrameDecoder {
+        Image,
+        /// The picture tile frames paint over
+        Tiles(RgbaImage),
+        Video {
+            decoder: ffmpeg::decoder::Video,
+            scaler: Option<(ffmpeg::software::scaling::Context, u32, u32)>,
+        },
+    }
+
+    impl FrameDecoder {
+        pub fn new(codec: FrameCodec) -> Result<Self> {
+            let id = match codec {
+                FrameCodec::Jpeg | FrameCodec::Png => return Ok(FrameDecoder::Image),
+                FrameCodec::Tiles => return Ok(FrameDecoder::Tiles(RgbaImage::default())),
+                FrameCodec::H264 => ffmpeg::codec::Id::H264,
+                FrameCodec::Hevc => ffmpeg::codec::Id::HEVC,
+            };
+            ffmpeg::init().context("Failed to initialize FFmpeg")?;
+            let codec = ffmpeg::decoder::find(id)
+                .with_context(|| format!("No {} decoder available", codec))?;
+            let decoder = ffmpeg::codec::Context::new_with_codec(codec)
+                .decoder()
+                .video()
+                .context("Failed to open decoder")?;
+            Ok(FrameDecoder::Video { decoder, scaler: None })
+        }
+
+        /// The picture for one frame's `data`, or `None` while the decoder
+        /// needs more input
+        pub fn decode(&mut self, data: &[u8]) -> Result<Option<RgbaImage>> {
+            let (decoder, scaler) = match self {
+                FrameDecoder::Image => {
+                    let image = image::load_from_memory(data).context("Failed to decode frame")?;
+                    return Ok(Some(image.to_rgba8()));
+                }
+                FrameDecoder::Tiles(canvas) => {
+                    TileFrame::parse(data)?.composite(canvas)?;
+                    return Ok(Some(canvas.clone()));
+                }
+                FrameDecoder::Video { decoder, scaler } => (decoder, scaler),
+            };
+
+            decoder.send_packet(&ffmpeg::Packet::copy(data))
+                .context("Failed to decode frame")?;
+            let mut decoded = ffmpeg::frame::Video::empty();
+            if decoder.receive_frame(&mut decoded).is_err() {
+                return Ok(None);
+            }
+
+            let (width, height) = (decoded.width(), decoded.height());
+            if scaler.as_ref().map(|(_, w, h)| (*w, *h)) != Some((width, height)) {
+                let context = ffmpeg::software::scaling::Context::get(
+                    decoded.format(),
+                    width,
+                    height,
+                    ffmpeg::format::Pixel::RGBA,
+                    width,
+                    height,
+                    ffmpeg::software::scaling::Flags::BILINEAR,
+                ).context("Failed to create scaler")?;
+                *scaler = Some((context, width, height));
+            }
+            let mut rgba = ffmpeg::frame::Video::empty();
+            let (context, _, _) = scaler.as_mut().expect("scaler created above");
+            context.run(&decoded, &mut rgba).context("Color conversion failed")?;
+
+            // Rows may be padded past the picture width
+            let stride = rgba.stride(0);
+            let row_len = width as usize * 4;
+            let data = rgba.data(0);
+            let mut pixels = Vec::with_capacity(row_len * height as usize);
+            for row in 0..height as usize {
+                pixels.extend_from_slice(&data[row * stride..row * stride + row_len]);
+            }
+            Ok(RgbaImage::from_raw(width, height, pixels))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::mp4::tests::{be32, find_box, h264_access_unit, parse_boxes};
+    use crate::recording::session_recorder::{RecordingConfig, SessionRecorder};
+    use tempfile::TempDir;
+
+    async fn record_h264(dir: &Path, frames: u32) -> RecordingMetadata {
+        let config = RecordingConfig {
+            recording_directory: dir.to_path_buf(),
+            min_free_space_bytes: 0,
+            ..Default::default()
+        };
+        let recorder = SessionRecorder::start("export", config, OperatorInfo::from_requester("tech")).unwrap();
+        for i in 0..frames {
+            let keyframe = i % 5 == 0;
+            recorder.record_frame(640, 480, keyframe, &h264_access_unit(keyframe));
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        recorder.stop().await.unwrap()
+    }
+
+    fn options() -> VideoExportOptions {
+        VideoExportOptions { format: VideoFormat::Mp4, caption: false }
+    }
+
+    #[test]
+    fn test_detect_codec() {
+        assert_eq!(FrameCodec::detect(&h264_access_unit(true)), Some(FrameCodec::H264));
+        assert_eq!(FrameCodec::detect(&[0, 0, 0, 1, 0x40, 0x01, 0x0c]), Some(FrameCodec::Hevc));
+        assert_eq!(FrameCodec::detect(&[0xff, 0xd8, 0xff, 0xe0]), Some(FrameCodec::Jpeg));
+        assert_eq!(FrameCodec::detect(b"\x89PNG\r\n"), Some(FrameCodec::Png));
+        assert_eq!(FrameCodec::detect(b"GFTL\x80\x07\0\0"), Some(FrameCodec::Tiles));
+        assert_eq!(FrameCodec::detect(&h264_access_unit(false)), None);
+    }
+
+    #[tokio::test]
+    async fn test_export_h264_recording_to_mp4() {
+        let dir = TempDir::new().unwrap();
+        let metadata = record_h264(dir.path(), 12).await;
+
+        let output = dir.path().join("out.mp4");
+        let summary = export_video(dir.path(), "export", Some(&output), options()).await.unwrap();
+        assert_eq!(summary.codec, FrameCodec::H264);
+        assert_eq!(summary.frames, 12);
+        assert!(!summary.transcoded);
+        assert!(summary.complete);
+
+        let data = fs::read(&output).unwrap();
+        let kinds: Vec<[u8; 4]> = parse_boxes(&data).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, vec![*b"ftyp", *b"mdat", *b"moov"]);
+        let tkhd = find_box(&data, &[b"moov", b"trak", b"tkhd"]);
+        assert_eq!((be32(tkhd, 88) >> 16, be32(tkhd, 92) >> 16), (640, 480));
+        let stss = find_box(&data, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stss"]);
+        assert_eq!((be32(stss, 4), be32(stss, 8), be32(stss, 12), be32(stss, 16)), (3, 1, 6, 11));
+
+        // Track duration follows the recorded timestamps, plus one frame for
+        // the last, within the rounding of a couple of 90 kHz ticks
+        let timestamps: Vec<u64> = recorded_frames(dir.path(), &metadata).map(|f| f.timestamp_us).collect();
+        let expected = (timestamps[11] - timestamps[0]) + (timestamps[11] - timestamps[10]);
+        assert!(summary.duration_us.abs_diff(expected) <= 25, "{} vs {}", summary.duration_us, expected);
+    }
+
+    #[tokio::test]
+    async fn test_export_crashed_recording() {
+        let dir = TempDir::new().unwrap();
+        let metadata = record_h264(dir.path(), 10).await;
+
+        // Simulate a crash: no end time in the index and a torn final record
+        let mut crashed = metadata.clone();
+        crashed.end_time = None;
+        fs::write(&metadata.files.metadata_file, serde_json::to_vec(&crashed).unwrap()).unwrap();
+        let segment = dir.path().join(&metadata.files.segments[0].file_name);
+        let len = fs::metadata(&segment).unwrap().len();
+        // Drop the SessionEnd event and half of the last frame
+        let file = fs::OpenOptions::new().write(true).open(&segment).unwrap();
+        let tail = 200 + 8;
+        file.set_len(len - tail).unwrap();
+
+        let output = dir.path().join("crashed.mp4");
+        let summary = export_video(dir.path(), "export", Some(&output), options()).await.unwrap();
+        assert!(!summary.complete);
+        assert!(summary.frames > 0 && summary.frames < 10);
+        assert!(!parse_boxes(&fs::read(&output).unwrap()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_exports_fail_cleanly() {
+        let dir = TempDir::new().unwrap();
+        record_h264(dir.path(), 3).await;
+
+        let output = dir.path().join("out.webm");
+        let webm = VideoExportOptions { format: VideoFormat::WebM, caption: false };
+        assert!(export_video(dir.path(), "export", Some(&output), webm).await.is_err());
+        assert!(!output.exists());
+
+        #[cfg(not(feature = "x264-encoder"))]
+        {
+            let output = dir.path().join("captioned.mp4");
+            let captioned = VideoExportOptions { format: VideoFormat::Mp4, caption: true };
+            assert!(export_video(dir.path(), "export", Some(&output), captioned).await.is_err());
+            assert!(!output.exists());
+        }
+    }
+
+    #[test]
+    fn test_caption_text() {
+        let operator = OperatorInfo::from_requester("Alex");
+        assert_eq!(caption_text(1_700_000_000_500_000, &operator), "2023-11-14 22:13:20 UTC  Alex");
+    }
+}
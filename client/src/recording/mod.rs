@@ -1,13 +1,19 @@
+#![allow(dead_code)]
+
+pub mod container;
+pub mod export;
+pub mod mp4;
+pub mod overlay;
 pub mod session_recorder;
+pub mod store;
+pub mod upload;
 
+pub use export::{export_video, VideoExportOptions, VideoFormat};
 pub use session_recorder::{
-    SessionRecorder, 
-    RecordingConfig, 
-    RecordingMetadata, 
-    OperatorInfo, 
-    SystemInfo, 
-    RecordingStats,
+    SessionRecorder,
+    RecordingConfig,
+    RecordingMetadata,
+    OperatorInfo,
     SessionEventType,
-    ComplianceInfo,
-    AuditLogEntry,
-};
\ No newline at end of file
+};
+pub use store::{export_recording, list_recordings};
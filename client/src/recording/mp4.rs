@@ -0,0 +1,560 @@
+//! Minimal MP4 muxer for recorded H.264 and HEVC streams
+//!
+//! Recorded frames are Annex B access units straight from the encoder. They
+//! are rewritten with 4-byte length prefixes into a single `mdat`, followed by
+//! a `moov` describing one video track. Parameter sets stay in-band (`avc3` /
+//! `hev1` sample entries), so display switches that change the resolution
+//! mid-recording still decode.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Media timescale; the usual 90 kHz video clock
+const TIMESCALE: u32 = 90_000;
+
+/// Movie header timescale (milliseconds)
+const MOVIE_TIMESCALE: u32 = 1000;
+
+/// Duration given to the last frame when there is no later one to measure against
+const DEFAULT_FRAME_DURATION: u32 = TIMESCALE / 30;
+
+/// Bytes of the `mdat` header, using a 64-bit size
+const MDAT_HEADER_LEN: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mp4Codec {
+    H264,
+    Hevc,
+}
+
+impl Mp4Codec {
+    fn nal_type(self, nal: &[u8]) -> u8 {
+        match self {
+            Mp4Codec::H264 => nal[0] & 0x1f,
+            Mp4Codec::Hevc => (nal[0] >> 1) & 0x3f,
+        }
+    }
+
+    fn is_keyframe_nal(self, nal_type: u8) -> bool {
+        match self {
+            Mp4Codec::H264 => nal_type == 5,
+            // IRAP pictures: BLA, IDR and CRA
+            Mp4Codec::Hevc => (16..=21).contains(&nal_type),
+        }
+    }
+
+    fn is_access_unit_delimiter(self, nal_type: u8) -> bool {
+        match self {
+            Mp4Codec::H264 => nal_type == 9,
+            Mp4Codec::Hevc => nal_type == 35,
+        }
+    }
+}
+
+/// Split an Annex B byte stream into NAL units, without start codes
+pub fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut units = Vec::with_capacity(starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let mut end = starts.get(n + 1).map(|next| next - 3).unwrap_or(data.len());
+        // Four-byte start codes and trailing zero bytes belong to no unit
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            units.push(&data[start..end]);
+        }
+    }
+    units
+}
+
+/// Whether an Annex B access unit can be decoded on its own
+pub fn is_keyframe(codec: Mp4Codec, access_unit: &[u8]) -> bool {
+    split_annex_b(access_unit)
+        .iter()
+        .any(|nal| codec.is_keyframe_nal(codec.nal_type(nal)))
+}
+
+/// Parameter sets for the sample entry's decoder configuration
+#[derive(Debug, Default)]
+struct ParameterSets {
+    vps: Option<Vec<u8>>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl ParameterSets {
+    fn collect(&mut self, codec: Mp4Codec, nal: &[u8]) {
+        let slot = match (codec, codec.nal_type(nal)) {
+            (Mp4Codec::H264, 7) | (Mp4Codec::Hevc, 33) => &mut self.sps,
+            (Mp4Codec::H264, 8) | (Mp4Codec::Hevc, 34) => &mut self.pps,
+            (Mp4Codec::Hevc, 32) => &mut self.vps,
+            _ => return,
+        };
+        slot.get_or_insert_with(|| nal.to_vec());
+    }
+
+    fn is_complete(&self, codec: Mp4Codec) -> bool {
+        self.sps.is_some() && self.pps.is_some() && (codec == Mp4Codec::H264 || self.vps.is_some())
+    }
+}
+
+/// What was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mp4Summary {
+    pub frames: u64,
+    pub duration_us: u64,
+}
+
+/// Streams samples into an MP4 file; the index is written by [`Mp4Writer::finish`]
+pub struct Mp4Writer<W: Write + Seek> {
+    out: W,
+    codec: Mp4Codec,
+    width: u32,
+    height: u32,
+    mdat_start: u64,
+    mdat_len: u64,
+    sample_sizes: Vec<u32>,
+    timestamps_us: Vec<u64>,
+    sync_samples: Vec<u32>,
+    parameter_sets: ParameterSets,
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    pub fn new(mut out: W, codec: Mp4Codec, width: u32, height: u32) -> io::Result<Self> {
+        out.write_all(&ftyp())?;
+        let mdat_start = out.stream_position()?;
+        // Size is patched in by finish()
+        out.write_all(&1u32.to_be_bytes())?;
+        out.write_all(b"mdat")?;
+        out.write_all(&0u64.to_be_bytes())?;
+
+        Ok(Self {
+            out,
+            codec,
+            width,
+            height,
+            mdat_start,
+            mdat_len: 0,
+            sample_sizes: Vec::new(),
+            timestamps_us: Vec::new(),
+            sync_samples: Vec::new(),
+            parameter_sets: ParameterSets::default(),
+        })
+    }
+
+    /// Append an Annex B access unit shown at `timestamp_us`.
+    ///
+    /// Frames before the first keyframe cannot be decoded and are skipped;
+    /// returns whether the frame was written.
+    pub fn write_sample(&mut self, timestamp_us: u64, access_unit: &[u8]) -> io::Result<bool> {
+        let nals: Vec<&[u8]> = split_annex_b(access_unit)
+            .into_iter()
+            .filter(|nal| !self.codec.is_access_unit_delimiter(self.codec.nal_type(nal)))
+            .collect();
+        let keyframe = nals.iter().any(|nal| self.codec.is_keyframe_nal(self.codec.nal_type(nal)));
+        if self.sample_sizes.is_empty() && !keyframe {
+            return Ok(false);
+        }
+
+        let mut size = 0u32;
+        for nal in &nals {
+            self.parameter_sets.collect(self.codec, nal);
+            self.out.write_all(&(nal.len() as u32).to_be_bytes())?;
+            self.out.write_all(nal)?;
+            size += 4 + nal.len() as u32;
+        }
+
+        // Presentation order must not go backwards
+        let timestamp_us = match self.timestamps_us.last() {
+            Some(&last) => timestamp_us.max(last + 1),
+            None => timestamp_us,
+        };
+        self.timestamps_us.push(timestamp_us);
+        self.sample_sizes.push(size);
+        self.mdat_len += size as u64;
+        if keyframe {
+            self.sync_samples.push(self.sample_sizes.len() as u32);
+        }
+        Ok(true)
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.sample_sizes.len() as u64
+    }
+
+    /// Write the index and return the underlying writer
+    pub fn finish(mut self) -> io::Result<(W, Mp4Summary)> {
+        if self.sample_sizes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "No decodable frames to export"));
+        }
+        if !self.parameter_sets.is_complete(self.codec) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Stream has no parameter sets"));
+        }
+
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(self.mdat_start + 8))?;
+        self.out.write_all(&(MDAT_HEADER_LEN + self.mdat_len).to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+
+        let durations = self.sample_durations();
+        let media_duration: u64 = durations.iter().map(|&d| d as u64).sum();
+        self.out.write_all(&self.moov(&durations, media_duration)?)?;
+        self.out.flush()?;
+
+        let summary = Mp4Summary {
+            frames: self.frames(),
+            duration_us: media_duration * 1_000_000 / TIMESCALE as u64,
+        };
+        Ok((self.out, summary))
+    }
+
+    /// Per-sample durations in media timescale units, from the frame timestamps
+    fn sample_durations(&self) -> Vec<u32> {
+        let first = self.timestamps_us[0];
+        let ticks: Vec<u64> = self.timestamps_us.iter()
+            .map(|&ts| (ts - first) * TIMESCALE as u64 / 1_000_000)
+            .collect();
+
+        let mut durations: Vec<u32> = ticks.windows(2)
+            .map(|pair| (pair[1] - pair[0]).clamp(1, u32::MAX as u64) as u32)
+            .collect();
+        durations.push(durations.last().copied().unwrap_or(DEFAULT_FRAME_DURATION));
+        durations
+    }
+
+    fn moov(&self, durations: &[u32], media_duration: u64) -> io::Result<Vec<u8>> {
+        let movie_duration = media_duration * MOVIE_TIMESCALE as u64 / TIMESCALE as u64;
+        let data_offset = self.mdat_start + MDAT_HEADER_LEN;
+
+        let stbl = mp4_box(b"stbl", &[
+            full_box(b"stsd", 0, 0, &[&1u32.to_be_bytes()[..], &self.sample_entry()?].concat()),
+            stts(durations),
+            counted_full_box(b"stss", &self.sync_samples),
+            full_box(b"stsc", 0, 0, &[1u32, 1, self.sample_sizes.len() as u32, 1].map(u32::to_be_bytes).concat()),
+            stsz(&self.sample_sizes),
+            chunk_offset(data_offset),
+        ].concat());
+
+        let minf = mp4_box(b"minf", &[
+            full_box(b"vmhd", 0, 1, &[0u8; 8]),
+            mp4_box(b"dinf", &full_box(b"dref", 0, 0, &[&1u32.to_be_bytes()[..], &full_box(b"url ", 0, 1, &[])].concat())),
+            stbl,
+        ].concat());
+
+        let mut mdhd = Vec::new();
+        mdhd.extend_from_slice(&[0u8; 16]); // creation and modification time
+        mdhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+        mdhd.extend_from_slice(&media_duration.to_be_bytes());
+        mdhd.extend_from_slice(&0x55c4u16.to_be_bytes()); // "und"
+        mdhd.extend_from_slice(&0u16.to_be_bytes());
+
+        let mut hdlr = vec![0u8; 4];
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0u8; 12]);
+        hdlr.extend_from_slice(b"VideoHandler\0");
+
+        let mdia = mp4_box(b"mdia", &[
+            full_box(b"mdhd", 1, 0, &mdhd),
+            full_box(b"hdlr", 0, 0, &hdlr),
+            minf,
+        ].concat());
+
+        let mut tkhd = Vec::new();
+        tkhd.extend_from_slice(&[0u8; 16]); // creation and modification time
+        tkhd.extend_from_slice(&1u32.to_be_bytes()); // track ID
+        tkhd.extend_from_slice(&[0u8; 4]);
+        tkhd.extend_from_slice(&movie_duration.to_be_bytes());
+        tkhd.extend_from_slice(&[0u8; 16]); // reserved, layer, alternate group, volume
+        tkhd.extend_from_slice(&unity_matrix());
+        tkhd.extend_from_slice(&(self.width << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(self.height << 16).to_be_bytes());
+
+        let mut mvhd = Vec::new();
+        mvhd.extend_from_slice(&[0u8; 16]); // creation and modification time
+        mvhd.extend_from_slice(&MOVIE_TIMESCALE.to_be_bytes());
+        mvhd.extend_from_slice(&movie_duration.to_be_bytes());
+        mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+        mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+        mvhd.extend_from_slice(&[0u8; 10]);
+        mvhd.extend_from_slice(&unity_matrix());
+        mvhd.extend_from_slice(&[0u8; 24]);
+        mvhd.extend_from_slice(&2u32.to_be_bytes()); // next track ID
+
+        Ok(mp4_box(b"moov", &[
+            full_box(b"mvhd", 1, 0, &mvhd),
+            mp4_box(b"trak", &[full_box(b"tkhd", 1, 3, &tkhd), mdia].concat()),
+        ].concat()))
+    }
+
+    fn sample_entry(&self) -> io::Result<Vec<u8>> {
+        let (kind, config) = match self.codec {
+            Mp4Codec::H264 => (b"avc3", mp4_box(b"avcC", &self.avc_config())),
+            Mp4Codec::Hevc => (b"hev1", mp4_box(b"hvcC", &self.hevc_config()?)),
+        };
+
+        let mut entry = vec![0u8; 6];
+        entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        entry.extend_from_slice(&[0u8; 16]);
+        entry.extend_from_slice(&(self.width.min(u16::MAX as u32) as u16).to_be_bytes());
+        entry.extend_from_slice(&(self.height.min(u16::MAX as u32) as u16).to_be_bytes());
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+        entry.extend_from_slice(&[0u8; 4]);
+        entry.extend_from_slice(&1u16.to_be_bytes()); // frame count
+        let mut compressor = [0u8; 32];
+        let name = b"GhostLink";
+        compressor[0] = name.len() as u8;
+        compressor[1..=name.len()].copy_from_slice(name);
+        entry.extend_from_slice(&compressor);
+        entry.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+        entry.extend_from_slice(&(-1i16).to_be_bytes());
+        entry.extend_from_slice(&config);
+        Ok(mp4_box(kind, &entry))
+    }
+
+    /// AVCDecoderConfigurationRecord
+    fn avc_config(&self) -> Vec<u8> {
+        let sps = self.parameter_sets.sps.as_deref().unwrap_or_default();
+        let pps = self.parameter_sets.pps.as_deref().unwrap_or_default();
+        let profile = sps.get(1..4).unwrap_or(&[0, 0, 0]);
+
+        let mut config = vec![1, profile[0], profile[1], profile[2], 0xff, 0xe1];
+        config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        config.extend_from_slice(sps);
+        config.push(1);
+        config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        config.extend_from_slice(pps);
+        config
+    }
+
+    /// HEVCDecoderConfigurationRecord, assuming 8-bit 4:2:0 as the encoders produce
+    fn hevc_config(&self) -> io::Result<Vec<u8>> {
+        let sps = self.parameter_sets.sps.as_deref().unwrap_or_default();
+        // Skip the NAL header; profile_tier_level follows one byte of IDs
+        let rbsp = unescape_rbsp(sps.get(2..).unwrap_or_default());
+        if rbsp.len() < 13 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HEVC SPS too short"));
+        }
+        let sub_layers = ((rbsp[0] >> 1) & 0x07) + 1;
+        let temporal_id_nested = rbsp[0] & 0x01;
+        let profile_tier_level = &rbsp[1..13];
+
+        let mut config = vec![1];
+        config.extend_from_slice(profile_tier_level);
+        config.extend_from_slice(&[0xf0, 0x00, 0xfc, 0xfd, 0xf8, 0xf8, 0x00, 0x00]);
+        config.push((sub_layers << 3) | (temporal_id_nested << 2) | 0x03);
+
+        let arrays = [
+            (32u8, &self.parameter_sets.vps),
+            (33, &self.parameter_sets.sps),
+            (34, &self.parameter_sets.pps),
+        ];
+        config.push(arrays.len() as u8);
+        for (nal_type, nal) in arrays {
+            let nal = nal.as_deref().unwrap_or_default();
+            config.push(nal_type);
+            config.extend_from_slice(&1u16.to_be_bytes());
+            config.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            config.extend_from_slice(nal);
+        }
+        Ok(config)
+    }
+}
+
+/// Drop emulation prevention bytes (00 00 03 -> 00 00)
+fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+fn ftyp() -> Vec<u8> {
+    mp4_box(b"ftyp", &[&b"isom"[..], &0x200u32.to_be_bytes(), b"isom", b"iso2", b"iso6", b"mp41"].concat())
+}
+
+fn stts(durations: &[u32]) -> Vec<u8> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &duration in durations {
+        match runs.last_mut() {
+            Some((count, delta)) if *delta == duration => *count += 1,
+            _ => runs.push((1, duration)),
+        }
+    }
+    let mut body = (runs.len() as u32).to_be_bytes().to_vec();
+    for (count, delta) in runs {
+        body.extend_from_slice(&count.to_be_bytes());
+        body.extend_from_slice(&delta.to_be_bytes());
+    }
+    full_box(b"stts", 0, 0, &body)
+}
+
+fn stsz(sizes: &[u32]) -> Vec<u8> {
+    let mut body = vec![0u8; 4]; // sizes vary
+    body.extend_from_slice(&(sizes.len() as u32).to_be_bytes());
+    for size in sizes {
+        body.extend_from_slice(&size.to_be_bytes());
+    }
+    full_box(b"stsz", 0, 0, &body)
+}
+
+/// All samples sit in one chunk at `offset`
+fn chunk_offset(offset: u64) -> Vec<u8> {
+    match u32::try_from(offset) {
+        Ok(offset) => full_box(b"stco", 0, 0, &[1u32, offset].map(u32::to_be_bytes).concat()),
+        Err(_) => full_box(b"co64", 0, 0, &[&1u32.to_be_bytes()[..], &offset.to_be_bytes()].concat()),
+    }
+}
+
+fn counted_full_box(kind: &[u8; 4], values: &[u32]) -> Vec<u8> {
+    let mut body = (values.len() as u32).to_be_bytes().to_vec();
+    for value in values {
+        body.extend_from_slice(&value.to_be_bytes());
+    }
+    full_box(kind, 0, 0, &body)
+}
+
+fn unity_matrix() -> Vec<u8> {
+    [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000]
+        .map(u32::to_be_bytes)
+        .concat()
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + body.len());
+    out.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let header = (version as u32) << 24 | (flags & 0x00ff_ffff);
+    mp4_box(kind, &[&header.to_be_bytes()[..], body].concat())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    pub(crate) const H264_SPS: &[u8] = &[0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80, 0xbf, 0xe5];
+    pub(crate) const H264_PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+
+    /// A synthetic access unit; keyframes carry parameter sets like x264 output
+    pub(crate) fn h264_access_unit(keyframe: bool) -> Vec<u8> {
+        let mut au = Vec::new();
+        if keyframe {
+            for nal in [H264_SPS, H264_PPS] {
+                au.extend_from_slice(&[0, 0, 0, 1]);
+                au.extend_from_slice(nal);
+            }
+            au.extend_from_slice(&[0, 0, 1, 0x65, 0x88, 0x84, 0x21]);
+        } else {
+            au.extend_from_slice(&[0, 0, 0, 1, 0x41, 0x9a, 0x02]);
+        }
+        au
+    }
+
+    /// Top-level boxes and their contents
+    pub(crate) fn parse_boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let mut size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+            let mut header = 8;
+            if size == 1 {
+                size = u64::from_be_bytes(data[pos + 8..pos + 16].try_into().unwrap()) as usize;
+                header = 16;
+            }
+            boxes.push((kind, &data[pos + header..pos + size]));
+            pos += size;
+        }
+        assert_eq!(pos, data.len(), "boxes must exactly cover their parent");
+        boxes
+    }
+
+    pub(crate) fn find_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        let (first, rest) = path.split_first().unwrap();
+        let (_, body) = parse_boxes(data).into_iter()
+            .find(|(kind, _)| kind == *first)
+            .unwrap_or_else(|| panic!("missing {}", String::from_utf8_lossy(*first)));
+        if rest.is_empty() { body } else { find_box(body, rest) }
+    }
+
+    pub(crate) fn be32(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_split_annex_b() {
+        let au = h264_access_unit(true);
+        let nals = split_annex_b(&au);
+        assert_eq!(nals, vec![H264_SPS, H264_PPS, &[0x65, 0x88, 0x84, 0x21][..]]);
+        assert!(is_keyframe(Mp4Codec::H264, &au));
+        assert!(!is_keyframe(Mp4Codec::H264, &h264_access_unit(false)));
+    }
+
+    #[test]
+    fn test_mp4_box_structure_and_duration() {
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Codec::H264, 1280, 720).unwrap();
+        // Leading delta frame cannot be decoded
+        assert!(!writer.write_sample(0, &h264_access_unit(false)).unwrap());
+        for i in 0..30u64 {
+            let keyframe = i % 10 == 0;
+            assert!(writer.write_sample(1_000_000 + i * 100_000, &h264_access_unit(keyframe)).unwrap());
+        }
+        let (out, summary) = writer.finish().unwrap();
+        let data = out.into_inner();
+
+        assert_eq!(summary.frames, 30);
+        // 30 frames, 100ms apart; the last lasts as long as the one before it
+        assert_eq!(summary.duration_us, 3_000_000);
+
+        let kinds: Vec<[u8; 4]> = parse_boxes(&data).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, vec![*b"ftyp", *b"mdat", *b"moov"]);
+
+        let mvhd = find_box(&data, &[b"moov", b"mvhd"]);
+        assert_eq!(be32(mvhd, 20), 1000);
+        assert_eq!(u64::from_be_bytes(mvhd[24..32].try_into().unwrap()), 3000);
+
+        let stbl = [b"moov", b"trak", b"mdia", b"minf", b"stbl"];
+        let stsd = find_box(&data, &[&stbl[..], &[b"stsd"]].concat());
+        assert_eq!(&stsd[12..16], b"avc3");
+        let stss = find_box(&data, &[&stbl[..], &[b"stss"]].concat());
+        assert_eq!((be32(stss, 4), be32(stss, 8), be32(stss, 12), be32(stss, 16)), (3, 1, 11, 21));
+        let stts = find_box(&data, &[&stbl[..], &[b"stts"]].concat());
+        assert_eq!((be32(stts, 4), be32(stts, 8), be32(stts, 12)), (1, 30, 9000));
+
+        // The chunk offset points at the first sample, a length-prefixed SPS
+        let stco = find_box(&data, &[&stbl[..], &[b"stco"]].concat());
+        let offset = be32(stco, 8) as usize;
+        assert_eq!(be32(&data, offset) as usize, H264_SPS.len());
+        assert_eq!(&data[offset + 4..offset + 4 + H264_SPS.len()], H264_SPS);
+    }
+
+    #[test]
+    fn test_stream_without_keyframe_is_rejected() {
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Codec::H264, 640, 480).unwrap();
+        writer.write_sample(0, &h264_access_unit(false)).unwrap();
+        assert!(writer.finish().is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Caption burned into exported recordings
+//!
+//! Draws a line of text (time and technician) on a dark band along the bottom
+//! of an RGBA frame using a built-in 5x7 font, so exports need no font files.
+//! Lowercase letters are drawn as capitals; characters without a glyph become
+//! spaces.
+
+/// Glyph width and height in font pixels
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+/// Font pixels between glyphs and around the text
+const SPACING: usize = 1;
+const PADDING: usize = 2;
+
+/// Rows of each glyph, most significant of the low five bits leftmost
+const FONT: &[(char, [u8; GLYPH_HEIGHT])] = &[
+    ('0', [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e]),
+    ('1', [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e]),
+    ('2', [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f]),
+    ('3', [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e]),
+    ('4', [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02]),
+    ('5', [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e]),
+    ('6', [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e]),
+    ('7', [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08]),
+    ('8', [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e]),
+    ('9', [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c]),
+    ('A', [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11]),
+    ('B', [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e]),
+    ('C', [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e]),
+    ('D', [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c]),
+    ('E', [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f]),
+    ('F', [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10]),
+    ('G', [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f]),
+    ('H', [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11]),
+    ('I', [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e]),
+    ('J', [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c]),
+    ('K', [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11]),
+    ('L', [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f]),
+    ('M', [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11]),
+    ('N', [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11]),
+    ('O', [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e]),
+    ('P', [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10]),
+    ('Q', [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d]),
+    ('R', [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11]),
+    ('S', [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e]),
+    ('T', [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04]),
+    ('U', [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e]),
+    ('V', [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04]),
+    ('W', [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a]),
+    ('X', [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11]),
+    ('Y', [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04]),
+    ('Z', [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f]),
+    (':', [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00]),
+    ('-', [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00]),
+    ('.', [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c]),
+    ('/', [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00]),
+    ('@', [0x0e, 0x11, 0x01, 0x0d, 0x15, 0x15, 0x0e]),
+    ('_', [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f]),
+    ('(', [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02]),
+    (')', [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08]),
+];
+
+fn glyph(c: char) -> Option<&'static [u8; GLYPH_HEIGHT]> {
+    let c = c.to_ascii_uppercase();
+    FONT.iter().find(|(g, _)| *g == c).map(|(_, rows)| rows)
+}
+
+/// Font pixel size for a frame: about 1/50 of the height per text line
+fn scale_for(height: u32) -> usize {
+    (height as usize / (50 * GLYPH_HEIGHT)).max(1)
+}
+
+/// Draw `text` on a dark band at the bottom-left of an RGBA frame
+pub fn draw_caption(rgba: &mut [u8], width: u32, height: u32, text: &str) {
+    let (width, height) = (width as usize, height as usize);
+    if rgba.len() < width * height * 4 {
+        return;
+    }
+
+    let scale = scale_for(height as u32);
+    let band_height = (GLYPH_HEIGHT + 2 * PADDING) * scale;
+    let band_width = (text.chars().count() * (GLYPH_WIDTH + SPACING) - SPACING + 2 * PADDING) * scale;
+    if band_height > height {
+        return;
+    }
+    let top = height - band_height;
+    let right = band_width.min(width);
+
+    for y in top..height {
+        for x in 0..right {
+            set_pixel(rgba, width, x, y, [0, 0, 0]);
+        }
+    }
+
+    for (i, c) in text.chars().enumerate() {
+        let Some(rows) = glyph(c) else { continue };
+        let origin_x = (PADDING + i * (GLYPH_WIDTH + SPACING)) * scale;
+        let origin_y = top + PADDING * scale;
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let x = origin_x + col * scale + dx;
+                        if x < width {
+                            set_pixel(rgba, width, x, origin_y + row * scale + dy, [255, 255, 255]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn set_pixel(rgba: &mut [u8], width: usize, x: usize, y: usize, color: [u8; 3]) {
+    let i = (y * width + x) * 4;
+    rgba[i..i + 3].copy_from_slice(&color);
+    rgba[i + 3] = 255;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caption_draws_band_and_glyphs() {
+        let (width, height) = (200u32, 100u32);
+        let mut rgba = vec![128u8; (width * height * 4) as usize];
+        draw_caption(&mut rgba, width, height, "1:");
+
+        let pixel = |x: u32, y: u32| {
+            let i = ((y * width + x) * 4) as usize;
+            [rgba[i], rgba[i + 1], rgba[i + 2]]
+        };
+        let top = height - (GLYPH_HEIGHT + 2 * PADDING) as u32;
+        // Band corner is dark, the frame above it untouched
+        assert_eq!(pixel(0, height - 1), [0, 0, 0]);
+        assert_eq!(pixel(0, top - 1), [128, 128, 128]);
+        // Top of the "1" stem: row 0 is 0x04, its middle column
+        assert_eq!(pixel(PADDING as u32 + 2, top + PADDING as u32), [255, 255, 255]);
+        assert_eq!(pixel(PADDING as u32, top + PADDING as u32), [0, 0, 0]);
+        // Band ends after the two glyphs
+        let band_width = (2 * (GLYPH_WIDTH + SPACING) - SPACING + 2 * PADDING) as u32;
+        assert_eq!(pixel(band_width, height - 1), [128, 128, 128]);
+    }
+
+    #[test]
+    fn test_caption_clipped_to_small_frames() {
+        let mut rgba = vec![0u8; 8 * 4 * 4];
+        draw_caption(&mut rgba, 8, 4, "TOO TALL");
+        assert!(rgba.iter().all(|&b| b == 0));
+
+        let mut rgba = vec![0u8; 10 * 20 * 4];
+        draw_caption(&mut rgba, 10, 20, "WIDER THAN THE FRAME");
+    }
+}
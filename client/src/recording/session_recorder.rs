@@ -1,109 +1,65 @@
-use crate::{
-    error::{GhostLinkError, Result},
-    capture::{Frame, frame_protocol::{FrameMessage, VideoCodec}},
-    input::input_protocol::InputEvent,
-};
+use crate::error::{GhostLinkError, Result};
+use crate::resources::{self, Lease};
+
+use super::container::{FrameRecord, Record, RecordingWriter, SegmentInfo, TimelineEntry};
 
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
-    fs::{File, OpenOptions},
-    io::{BufWriter, Write, Seek, SeekFrom},
+    collections::HashMap,
     path::{Path, PathBuf},
-    sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::{
-    sync::{RwLock, mpsc},
-    task::JoinHandle,
-    time::{interval, sleep},
-};
-use tracing::{debug, error, info, trace, warn};
-use serde::{Deserialize, Serialize};
-use parking_lot::RwLock as ParkingRwLock;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
 
-const RECORDING_BUFFER_SIZE: usize = 1000;  // Buffer up to 1000 events before writing
-const RECORDING_FLUSH_INTERVAL_MS: u64 = 1000; // Flush to disk every second
+const RECORDING_QUEUE_SIZE: usize = 256; // Records waiting for the writer thread
 const MAX_RECORDING_SIZE_MB: u64 = 10240; // 10GB max recording size
-const RECORDING_FILE_EXTENSION: &str = ".glr"; // GhostLink Recording
+const DEFAULT_SEGMENT_SIZE_MB: u64 = 256;
+const DEFAULT_MIN_FREE_SPACE_MB: u64 = 1024;
 
-/// Comprehensive session recording system for compliance and audit trails
-pub struct SessionRecorder {
-    /// Session identifier
-    session_id: String,
-    /// Recording configuration
-    config: RecordingConfig,
-    /// Recording file path
-    recording_path: PathBuf,
-    /// Video frame writer
-    video_writer: Arc<RwLock<Option<VideoRecordingWriter>>>,
-    /// Input event writer  
-    input_writer: Arc<RwLock<Option<InputRecordingWriter>>>,
-    /// Recording metadata
-    metadata: Arc<RwLock<RecordingMetadata>>,
-    /// Recording state
-    is_recording: AtomicBool,
-    /// Recording start time
-    start_time: Arc<RwLock<Option<SystemTime>>>,
-    /// Recording statistics
-    stats: Arc<ParkingRwLock<RecordingStats>>,
-    /// Event buffer for batching writes
-    event_buffer: Arc<RwLock<VecDeque<RecordingEvent>>>,
-    /// Flush task handle
-    flush_task: Arc<RwLock<Option<JoinHandle<()>>>>,
-    /// Recording size tracker
-    recording_size_bytes: AtomicU64,
-}
+/// Suffix of the JSON sidecar next to a recording's segments
+pub const METADATA_SUFFIX: &str = ".json";
 
 /// Recording configuration options
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RecordingConfig {
+    /// Record sessions whose request does not say either way
+    pub enabled: bool,
     /// Enable video recording
     pub record_video: bool,
     /// Enable input event recording
     pub record_input: bool,
-    /// Enable audio recording (future)
-    pub record_audio: bool,
-    /// Video quality for recording (1-10)
-    pub video_quality: u8,
-    /// Frame rate for recording (fps)
-    pub frame_rate: u32,
-    /// Compression level (1-9)
-    pub compression_level: u8,
-    /// Maximum recording duration (seconds)
-    pub max_duration_seconds: Option<u64>,
-    /// Maximum recording size (bytes)
-    pub max_size_bytes: Option<u64>,
     /// Recording directory
     pub recording_directory: PathBuf,
-    /// Auto-save interval (seconds)
-    pub auto_save_interval: u64,
-    /// Include system information
-    pub include_system_info: bool,
-    /// Include network information
-    pub include_network_info: bool,
-    /// Encryption enabled
-    pub encrypt_recording: bool,
-    /// Encryption key (for encrypted recordings)
-    pub encryption_key: Option<Vec<u8>>,
+    /// Start a new segment file once the current one reaches this size (bytes)
+    pub segment_size_bytes: u64,
+    /// Maximum recording size (bytes); recording stops once it is reached
+    pub max_size_bytes: Option<u64>,
+    /// Free space the recording disk must keep; recording stops below it (bytes)
+    pub min_free_space_bytes: u64,
+    /// How often buffered records and the index are written out (seconds)
+    pub flush_interval_secs: u64,
 }
 
 impl Default for RecordingConfig {
     fn default() -> Self {
         Self {
+            enabled: false,
             record_video: true,
             record_input: true,
-            record_audio: false, // Not implemented yet
-            video_quality: 7,
-            frame_rate: 30,
-            compression_level: 6,
-            max_duration_seconds: Some(3600 * 4), // 4 hours
+            recording_directory: dirs::data_local_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("ghostlink")
+                .join("recordings"),
+            segment_size_bytes: DEFAULT_SEGMENT_SIZE_MB * 1024 * 1024,
             max_size_bytes: Some(MAX_RECORDING_SIZE_MB * 1024 * 1024),
-            recording_directory: PathBuf::from("./recordings"),
-            auto_save_interval: 60, // 1 minute
-            include_system_info: true,
-            include_network_info: true,
-            encrypt_recording: false,
-            encryption_key: None,
+            min_free_space_bytes: DEFAULT_MIN_FREE_SPACE_MB * 1024 * 1024,
+            flush_interval_secs: 5,
         }
     }
 }
@@ -111,11 +67,13 @@ impl Default for RecordingConfig {
 /// Recording metadata stored with each session
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RecordingMetadata {
+    /// Recording ID; names the sidecar and segment files
+    pub recording_id: String,
     /// Session ID
     pub session_id: String,
     /// Recording start time
     pub start_time: u64,
-    /// Recording end time
+    /// Recording end time; unset while recording or if the agent crashed
     pub end_time: Option<u64>,
     /// Duration in seconds
     pub duration_seconds: f64,
@@ -152,6 +110,21 @@ pub struct OperatorInfo {
     pub user_agent: String,
 }
 
+impl OperatorInfo {
+    /// Operator known only by the name the relay passed along with the session request
+    pub fn from_requester(requester: &str) -> Self {
+        Self {
+            user_id: requester.to_string(),
+            display_name: requester.to_string(),
+            email: String::new(),
+            organization: String::new(),
+            auth_method: "relay".to_string(),
+            client_ip: String::new(),
+            user_agent: "GhostLink Client".to_string(),
+        }
+    }
+}
+
 /// Information about the target system being accessed
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SystemInfo {
@@ -165,60 +138,43 @@ pub struct SystemInfo {
     pub cpu_info: String,
     /// Memory information
     pub memory_info: String,
-    /// Network interfaces
-    pub network_interfaces: Vec<NetworkInterface>,
-    /// Screen resolution
-    pub screen_resolution: String,
     /// Agent version
     pub agent_version: String,
     /// System uptime
     pub system_uptime: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct NetworkInterface {
-    pub name: String,
-    pub ip_address: String,
-    pub mac_address: String,
-    pub interface_type: String,
-}
-
 /// Recording statistics
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RecordingStats {
     /// Total frames recorded
     pub frames_recorded: u64,
+    /// Frames dropped because the writer fell behind or buffers reached
+    /// the agent's memory ceiling
+    pub frames_dropped: u64,
     /// Total input events recorded
     pub input_events_recorded: u64,
+    /// Timeline events other than input (session, commands, chat)
+    pub timeline_events_recorded: u64,
     /// Total bytes written
     pub bytes_written: u64,
     /// Average frame rate
     pub average_fps: f64,
-    /// Peak frame rate
-    pub peak_fps: f64,
     /// Recording errors
     pub recording_errors: u64,
-    /// Compression ratio achieved
-    pub compression_ratio: f64,
-    /// Recording efficiency
-    pub recording_efficiency: f64,
+    /// Flushes ahead of the interval to give buffers back near the
+    /// memory ceiling
+    #[serde(default)]
+    pub early_flushes: u64,
 }
 
-/// File paths for different recording components
+/// Files that make up a recording
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RecordingFiles {
-    /// Main recording file
-    pub main_file: PathBuf,
-    /// Video data file
-    pub video_file: Option<PathBuf>,
-    /// Input events file
-    pub input_file: Option<PathBuf>,
-    /// Audio file (future)
-    pub audio_file: Option<PathBuf>,
     /// Metadata file
     pub metadata_file: PathBuf,
-    /// Thumbnail image
-    pub thumbnail_file: Option<PathBuf>,
+    /// Segment files, in order; names are relative to the metadata file's directory
+    pub segments: Vec<SegmentInfo>,
 }
 
 /// Compliance information for audit trails
@@ -234,8 +190,6 @@ pub struct ComplianceInfo {
     pub retention_days: u32,
     /// Legal hold status
     pub legal_hold: bool,
-    /// Digital signature (for integrity)
-    pub digital_signature: Option<String>,
     /// Audit log entries
     pub audit_log: Vec<AuditLogEntry>,
 }
@@ -249,39 +203,7 @@ pub struct AuditLogEntry {
     pub client_ip: String,
 }
 
-/// Individual recording events
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum RecordingEvent {
-    /// Video frame with timestamp
-    VideoFrame {
-        timestamp: u64,
-        frame_data: Vec<u8>,
-        codec: VideoCodec,
-        width: u32,
-        height: u32,
-        is_keyframe: bool,
-    },
-    /// Input event with timestamp
-    InputEvent {
-        timestamp: u64,
-        event: InputEvent,
-        source_ip: String,
-    },
-    /// Session event (start, pause, resume, end)
-    SessionEvent {
-        timestamp: u64,
-        event_type: SessionEventType,
-        description: String,
-    },
-    /// System event (resolution change, monitor change, etc.)
-    SystemEvent {
-        timestamp: u64,
-        event_type: String,
-        details: serde_json::Value,
-    },
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SessionEventType {
     SessionStart,
     SessionPause,
@@ -293,63 +215,68 @@ pub enum SessionEventType {
     QualityChange,
 }
 
-/// Video recording writer for efficient video data storage
-pub struct VideoRecordingWriter {
-    file: BufWriter<File>,
-    frame_count: u64,
-    start_time: SystemTime,
-    last_keyframe_time: u64,
+/// Work for the writer thread
+enum WriterCommand {
+    /// A record, with the frame bytes it holds in the memory pool
+    Record(Record, Option<Lease<'static>>),
+    /// Write out what is buffered now, as memory runs short
+    Flush,
+    Finish(oneshot::Sender<RecordingMetadata>),
 }
 
-/// Input recording writer for input event storage
-pub struct InputRecordingWriter {
-    file: BufWriter<File>,
-    event_count: u64,
+/// Records one session to disk.
+///
+/// Frames and timeline events are queued to a writer thread, so callers on
+/// the capture path never wait for the disk. The writer flushes segments and
+/// rewrites the JSON index every `flush_interval_secs`, leaving a playable
+/// recording behind even if the agent dies mid-session. Queued frames are
+/// charged to the agent's memory pool; near its ceiling the writer flushes
+/// early, and frames that would cross it are dropped.
+pub struct SessionRecorder {
+    session_id: String,
+    config: RecordingConfig,
+    metadata_path: PathBuf,
+    tx: Sender<WriterCommand>,
+    /// Cleared when recording stops, including on size or disk space limits
+    is_recording: Arc<AtomicBool>,
+    /// Set while an early flush waits for the writer
+    flush_pending: Arc<AtomicBool>,
+    stats: Arc<Mutex<RecordingStats>>,
+    writer_thread: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl SessionRecorder {
-    /// Create new session recorder
-    pub async fn new(session_id: String, config: RecordingConfig) -> Result<Self> {
-        info!("Creating session recorder for session: {}", session_id);
-        
-        // Ensure recording directory exists
-        if !config.recording_directory.exists() {
-            std::fs::create_dir_all(&config.recording_directory)
-                .map_err(|e| GhostLinkError::Other(format!("Failed to create recording directory: {}", e)))?;
+    /// Create the recording files and start recording
+    pub fn start(session_id: &str, config: RecordingConfig, operator: OperatorInfo) -> Result<Self> {
+        std::fs::create_dir_all(&config.recording_directory)
+            .map_err(|e| GhostLinkError::Other(format!("Failed to create recording directory: {}", e)))?;
+        if let Some(available) = available_space(&config.recording_directory) {
+            if available < config.min_free_space_bytes {
+                return Err(GhostLinkError::Other(format!(
+                    "Not enough disk space to record: {} MB free in {}",
+                    available / 1024 / 1024,
+                    config.recording_directory.display()
+                )));
+            }
         }
-        
-        // Create recording file path
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        let filename = format!("session_{}_{}{}", session_id, timestamp, RECORDING_FILE_EXTENSION);
-        let recording_path = config.recording_directory.join(filename);
-        
-        // Initialize metadata
+
+        let start_time = unix_seconds();
+        let recording_id = format!("session_{}_{}", sanitize(session_id), start_time);
+        let metadata_path = config.recording_directory.join(format!("{}{}", recording_id, METADATA_SUFFIX));
+        let writer = RecordingWriter::create(&config.recording_directory, &recording_id, config.segment_size_bytes)?;
+
         let metadata = RecordingMetadata {
-            session_id: session_id.clone(),
-            start_time: timestamp,
+            recording_id: recording_id.clone(),
+            session_id: session_id.to_string(),
+            start_time,
             end_time: None,
             duration_seconds: 0.0,
-            operator: OperatorInfo {
-                user_id: "unknown".to_string(),
-                display_name: "Unknown User".to_string(),
-                email: "unknown@example.com".to_string(),
-                organization: "Unknown".to_string(),
-                auth_method: "unknown".to_string(),
-                client_ip: "0.0.0.0".to_string(),
-                user_agent: "GhostLink Client".to_string(),
-            },
-            target_system: Self::gather_system_info().await,
+            operator,
+            target_system: gather_system_info(),
             stats: RecordingStats::default(),
             files: RecordingFiles {
-                main_file: recording_path.clone(),
-                video_file: None,
-                input_file: None,
-                audio_file: None,
-                metadata_file: recording_path.with_extension("json"),
-                thumbnail_file: None,
+                metadata_file: metadata_path.clone(),
+                segments: writer.segments().to_vec(),
             },
             config: config.clone(),
             compliance: ComplianceInfo {
@@ -358,534 +285,471 @@ impl SessionRecorder {
                 data_classification: "internal".to_string(),
                 retention_days: 90,
                 legal_hold: false,
-                digital_signature: None,
                 audit_log: Vec::new(),
             },
         };
-        
-        Ok(Self {
-            session_id,
+        save_metadata(&metadata)?;
+
+        let (tx, rx) = bounded(RECORDING_QUEUE_SIZE);
+        let is_recording = Arc::new(AtomicBool::new(true));
+        let flush_pending = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Mutex::new(RecordingStats::default()));
+        let thread = RecordingThread {
+            rx,
+            writer,
+            metadata,
+            is_recording: Arc::clone(&is_recording),
+            flush_pending: Arc::clone(&flush_pending),
+            stats: Arc::clone(&stats),
+            started: Instant::now(),
+            halted: false,
+        };
+        let writer_thread = std::thread::Builder::new()
+            .name(format!("recording-{}", session_id))
+            .spawn(move || thread.run())?;
+
+        info!("Recording session {} to {}", session_id, metadata_path.display());
+        let recorder = Self {
+            session_id: session_id.to_string(),
             config,
-            recording_path,
-            video_writer: Arc::new(RwLock::new(None)),
-            input_writer: Arc::new(RwLock::new(None)),
-            metadata: Arc::new(RwLock::new(metadata)),
-            is_recording: AtomicBool::new(false),
-            start_time: Arc::new(RwLock::new(None)),
-            stats: Arc::new(ParkingRwLock::new(RecordingStats::default())),
-            event_buffer: Arc::new(RwLock::new(VecDeque::with_capacity(RECORDING_BUFFER_SIZE))),
-            flush_task: Arc::new(RwLock::new(None)),
-            recording_size_bytes: AtomicU64::new(0),
-        })
-    }
-    
-    /// Start recording
-    pub async fn start_recording(&self, operator_info: OperatorInfo) -> Result<()> {
-        if self.is_recording.load(Ordering::Relaxed) {
-            warn!("Recording already started for session {}", self.session_id);
-            return Ok(());
-        }
-        
-        info!("Starting recording for session: {}", self.session_id);
-        
-        // Update metadata with operator info
-        {
-            let mut metadata = self.metadata.write().await;
-            metadata.operator = operator_info;
-            metadata.start_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-        }
-        
-        // Initialize writers
-        if self.config.record_video {
-            self.init_video_writer().await?;
-        }
-        
-        if self.config.record_input {
-            self.init_input_writer().await?;
-        }
-        
-        // Start recording
-        *self.start_time.write().await = Some(SystemTime::now());
-        self.is_recording.store(true, Ordering::Relaxed);
-        
-        // Start flush task
-        self.start_flush_task().await;
-        
-        // Record session start event
-        self.record_session_event(SessionEventType::SessionStart, "Recording started").await?;
-        
-        info!("Recording started successfully for session: {}", self.session_id);
-        Ok(())
+            metadata_path,
+            tx,
+            is_recording,
+            flush_pending,
+            stats,
+            writer_thread: Mutex::new(Some(writer_thread)),
+        };
+        recorder.record_session_event(SessionEventType::SessionStart, "Recording started");
+        Ok(recorder)
     }
-    
-    /// Stop recording
-    pub async fn stop_recording(&self) -> Result<()> {
-        if !self.is_recording.load(Ordering::Relaxed) {
-            return Ok(());
+
+    fn enqueue(&self, record: Record, lease: Option<Lease<'static>>) -> bool {
+        if !self.is_recording() {
+            return false;
         }
-        
-        info!("Stopping recording for session: {}", self.session_id);
-        
-        // Record session end event
-        self.record_session_event(SessionEventType::SessionEnd, "Recording stopped").await?;
-        
-        // Stop recording
-        self.is_recording.store(false, Ordering::Relaxed);
-        
-        // Stop flush task
-        if let Some(task) = self.flush_task.write().await.take() {
-            task.abort();
-            let _ = task.await;
+        match self.tx.try_send(WriterCommand::Record(record, lease)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("Recording queue full for session {}", self.session_id);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
         }
-        
-        // Final flush
-        self.flush_events().await?;
-        
-        // Close writers
-        self.close_writers().await?;
-        
-        // Update metadata
-        self.update_final_metadata().await?;
-        
-        // Save metadata
-        self.save_metadata().await?;
-        
-        info!("Recording stopped and saved for session: {}", self.session_id);
-        Ok(())
     }
-    
-    /// Record a video frame
-    pub async fn record_frame(&self, frame_message: &FrameMessage) -> Result<()> {
-        if !self.is_recording.load(Ordering::Relaxed) || !self.config.record_video {
-            return Ok(());
+
+    /// Record an encoded video frame
+    pub fn record_frame(&self, width: u32, height: u32, keyframe: bool, data: &[u8]) {
+        if !self.config.record_video || !self.is_recording() {
+            return;
         }
-        
-        // Check recording size limits
-        if let Some(max_size) = self.config.max_size_bytes {
-            if self.recording_size_bytes.load(Ordering::Relaxed) >= max_size {
-                warn!("Recording size limit reached, stopping recording");
-                self.stop_recording().await?;
-                return Ok(());
-            }
+        let pool = resources::memory_pool();
+        if pool.under_pressure() && !self.flush_pending.swap(true, Ordering::AcqRel) {
+            let _ = self.tx.try_send(WriterCommand::Flush);
         }
-        
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_micros() as u64;
-        
-        let codec = VideoCodec::H264; // TODO: Get from frame_message
-        let event = RecordingEvent::VideoFrame {
-            timestamp,
-            frame_data: frame_message.data.clone(),
-            codec,
-            width: frame_message.header.width,
-            height: frame_message.header.height,
-            is_keyframe: frame_message.header.is_keyframe(),
+        let Some(lease) = pool.reserve(data.len()) else {
+            self.stats.lock().frames_dropped += 1;
+            return;
         };
-        
-        self.buffer_event(event).await;
-        
-        // Update stats
-        {
-            let mut stats = self.stats.write();
-            stats.frames_recorded += 1;
-            stats.bytes_written += frame_message.data.len() as u64;
+        let record = Record::Frame(FrameRecord {
+            timestamp_us: unix_micros(),
+            width,
+            height,
+            keyframe,
+            data: data.to_vec(),
+        });
+        if !self.enqueue(record, Some(lease)) && self.is_recording() {
+            self.stats.lock().frames_dropped += 1;
         }
-        
-        trace!("Recorded video frame: {} bytes", frame_message.data.len());
-        Ok(())
     }
-    
-    /// Record an input event
-    pub async fn record_input_event(&self, input_event: &InputEvent, source_ip: &str) -> Result<()> {
-        if !self.is_recording.load(Ordering::Relaxed) || !self.config.record_input {
-            return Ok(());
-        }
-        
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_micros() as u64;
-        
-        let event = RecordingEvent::InputEvent {
-            timestamp,
-            event: input_event.clone(),
-            source_ip: source_ip.to_string(),
-        };
-        
-        self.buffer_event(event).await;
-        
-        // Update stats
-        {
-            let mut stats = self.stats.write();
-            stats.input_events_recorded += 1;
+
+    /// Record an input event from the remote operator
+    pub fn record_input(&self, event_type: &str, data: &serde_json::Value) {
+        if !self.config.record_input {
+            return;
         }
-        
-        trace!("Recorded input event: {:?}", input_event.event_type());
-        Ok(())
+        self.record_entry(TimelineEntry::Input {
+            event_type: event_type.to_string(),
+            data: data.clone(),
+        });
+    }
+
+    /// Record a command run on the remote machine
+    pub fn record_command(&self, command: &str, exit_code: Option<i32>) {
+        self.record_entry(TimelineEntry::Command {
+            command: command.to_string(),
+            exit_code,
+        });
+    }
+
+    /// Record a chat message between technician and user
+    pub fn record_chat(&self, sender: &str, message: &str) {
+        self.record_entry(TimelineEntry::Chat {
+            sender: sender.to_string(),
+            message: message.to_string(),
+        });
     }
-    
+
     /// Record a session event
-    pub async fn record_session_event(&self, event_type: SessionEventType, description: &str) -> Result<()> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_micros() as u64;
-        
-        let event = RecordingEvent::SessionEvent {
-            timestamp,
-            event_type,
+    pub fn record_session_event(&self, event_type: SessionEventType, description: &str) {
+        self.record_entry(TimelineEntry::Session {
+            event: event_type,
             description: description.to_string(),
-        };
-        
-        self.buffer_event(event).await;
-        
-        // Also add to compliance audit log
-        {
-            let mut metadata = self.metadata.write().await;
-            metadata.compliance.audit_log.push(AuditLogEntry {
-                timestamp,
-                event_type: "session".to_string(),
-                description: description.to_string(),
-                operator_id: metadata.operator.user_id.clone(),
-                client_ip: metadata.operator.client_ip.clone(),
-            });
+        });
+    }
+
+    /// Record any other timeline event
+    pub fn record_system_event(&self, event_type: &str, details: HashMap<String, String>) {
+        self.record_entry(TimelineEntry::System {
+            event_type: event_type.to_string(),
+            details,
+        });
+    }
+
+    fn record_entry(&self, entry: TimelineEntry) {
+        if !self.enqueue(Record::Event { timestamp_us: unix_micros(), entry }, None) && self.is_recording() {
+            warn!("Dropped timeline event for session {}", self.session_id);
         }
-        
-        debug!("Recorded session event: {:?} - {}", event_type, description);
-        Ok(())
     }
-    
-    /// Buffer event for batch writing
-    async fn buffer_event(&self, event: RecordingEvent) {
-        let mut buffer = self.event_buffer.write().await;
-        buffer.push_back(event);
-        
-        // If buffer is full, trigger immediate flush
-        if buffer.len() >= RECORDING_BUFFER_SIZE {
-            drop(buffer);
-            if let Err(e) = self.flush_events().await {
-                error!("Failed to flush recording events: {}", e);
+
+    /// Stop recording and write the final index
+    pub async fn stop(&self) -> Result<RecordingMetadata> {
+        if self.is_recording() {
+            self.record_session_event(SessionEventType::SessionEnd, "Recording stopped");
+        }
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let finished = self.tx.send(WriterCommand::Finish(done_tx)).is_ok();
+        self.is_recording.store(false, Ordering::Relaxed);
+        let metadata = if finished { done_rx.await.ok() } else { None };
+
+        let thread = self.writer_thread.lock().take();
+        if let Some(thread) = thread {
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+
+        match metadata {
+            Some(metadata) => {
+                info!("Recording of session {} saved to {}", self.session_id, self.metadata_path.display());
+                Ok(metadata)
             }
+            // The writer already finished, e.g. after a stop or a write failure
+            None => load_metadata(&self.metadata_path),
         }
     }
-    
-    /// Start periodic flush task
-    async fn start_flush_task(&self) {
-        let event_buffer = Arc::clone(&self.event_buffer);
-        let video_writer = Arc::clone(&self.video_writer);
-        let input_writer = Arc::clone(&self.input_writer);
-        let is_recording = Arc::clone(&self.is_recording);
-        let recording_size_bytes = Arc::clone(&self.recording_size_bytes);
-        
-        let task = tokio::spawn(async move {
-            let mut flush_interval = interval(Duration::from_millis(RECORDING_FLUSH_INTERVAL_MS));
-            flush_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-            
-            while is_recording.load(Ordering::Relaxed) {
-                flush_interval.tick().await;
-                
-                if let Err(e) = Self::flush_events_internal(
-                    &event_buffer,
-                    &video_writer,
-                    &input_writer,
-                    &recording_size_bytes,
-                ).await {
-                    error!("Failed to flush recording events: {}", e);
-                }
-            }
-        });
-        
-        *self.flush_task.write().await = Some(task);
+
+    /// Get recording statistics
+    pub fn get_stats(&self) -> RecordingStats {
+        self.stats.lock().clone()
     }
-    
-    /// Flush buffered events to disk
-    async fn flush_events(&self) -> Result<()> {
-        Self::flush_events_internal(
-            &self.event_buffer,
-            &self.video_writer,
-            &self.input_writer,
-            &self.recording_size_bytes,
-        ).await
+
+    /// Check if currently recording
+    pub fn is_recording(&self) -> bool {
+        self.is_recording.load(Ordering::Relaxed)
     }
-    
-    /// Internal flush implementation
-    async fn flush_events_internal(
-        event_buffer: &Arc<RwLock<VecDeque<RecordingEvent>>>,
-        video_writer: &Arc<RwLock<Option<VideoRecordingWriter>>>,
-        input_writer: &Arc<RwLock<Option<InputRecordingWriter>>>,
-        recording_size_bytes: &AtomicU64,
-    ) -> Result<()> {
-        let events: Vec<RecordingEvent> = {
-            let mut buffer = event_buffer.write().await;
-            let events: Vec<_> = buffer.drain(..).collect();
-            events
-        };
-        
-        if events.is_empty() {
-            return Ok(());
-        }
-        
-        trace!("Flushing {} recording events to disk", events.len());
-        
-        let mut bytes_written = 0;
-        
-        for event in events {
-            match event {
-                RecordingEvent::VideoFrame { frame_data, .. } => {
-                    if let Some(ref mut writer) = *video_writer.write().await {
-                        let serialized = bincode::serialize(&event)
-                            .map_err(|e| GhostLinkError::Other(format!("Serialization error: {}", e)))?;
-                        writer.file.write_all(&serialized)?;
-                        writer.frame_count += 1;
-                        bytes_written += serialized.len();
-                    }
+
+    /// Path of the JSON sidecar
+    pub fn metadata_path(&self) -> &Path {
+        &self.metadata_path
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        // Dropping the sender lets the writer thread finalize on its own
+        self.is_recording.store(false, Ordering::Relaxed);
+    }
+}
+
+/// State owned by the writer thread
+struct RecordingThread {
+    rx: Receiver<WriterCommand>,
+    writer: RecordingWriter,
+    metadata: RecordingMetadata,
+    is_recording: Arc<AtomicBool>,
+    flush_pending: Arc<AtomicBool>,
+    stats: Arc<Mutex<RecordingStats>>,
+    started: Instant,
+    /// Set once a size or disk space limit is hit; later records are discarded
+    halted: bool,
+}
+
+impl RecordingThread {
+    fn run(mut self) {
+        let flush_interval = Duration::from_secs(self.metadata.config.flush_interval_secs.max(1));
+        let mut last_flush = Instant::now();
+
+        loop {
+            let timeout = flush_interval.saturating_sub(last_flush.elapsed());
+            match self.rx.recv_timeout(timeout) {
+                Ok(WriterCommand::Record(record, _lease)) => self.write(record),
+                Ok(WriterCommand::Flush) => {
+                    self.stats.lock().early_flushes += 1;
+                    self.checkpoint();
+                    last_flush = Instant::now();
+                    self.flush_pending.store(false, Ordering::Release);
                 }
-                RecordingEvent::InputEvent { .. } => {
-                    if let Some(ref mut writer) = *input_writer.write().await {
-                        let serialized = bincode::serialize(&event)
-                            .map_err(|e| GhostLinkError::Other(format!("Serialization error: {}", e)))?;
-                        writer.file.write_all(&serialized)?;
-                        writer.event_count += 1;
-                        bytes_written += serialized.len();
-                    }
+                Ok(WriterCommand::Finish(done)) => {
+                    self.finish();
+                    let _ = done.send(self.metadata.clone());
+                    return;
                 }
-                _ => {
-                    // Session and system events can go to either writer
-                    if let Some(ref mut writer) = *input_writer.write().await {
-                        let serialized = bincode::serialize(&event)
-                            .map_err(|e| GhostLinkError::Other(format!("Serialization error: {}", e)))?;
-                        writer.file.write_all(&serialized)?;
-                        bytes_written += serialized.len();
-                    }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.finish();
+                    return;
                 }
             }
+
+            if last_flush.elapsed() >= flush_interval {
+                self.checkpoint();
+                last_flush = Instant::now();
+            }
         }
-        
-        // Flush writers
-        if let Some(ref mut writer) = *video_writer.write().await {
-            writer.file.flush()?;
+    }
+
+    fn write(&mut self, record: Record) {
+        if self.halted {
+            return;
         }
-        if let Some(ref mut writer) = *input_writer.write().await {
-            writer.file.flush()?;
+        if let Record::Event { entry, timestamp_us } = &record {
+            self.audit(*timestamp_us, entry);
         }
-        
-        recording_size_bytes.fetch_add(bytes_written as u64, Ordering::Relaxed);
-        trace!("Flushed {} bytes to recording files", bytes_written);
-        
-        Ok(())
-    }
-    
-    /// Initialize video recording writer
-    async fn init_video_writer(&self) -> Result<()> {
-        let video_path = self.recording_path.with_extension("video");
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&video_path)
-            .map_err(|e| GhostLinkError::Other(format!("Failed to create video file: {}", e)))?;
-        
-        let writer = VideoRecordingWriter {
-            file: BufWriter::new(file),
-            frame_count: 0,
-            start_time: SystemTime::now(),
-            last_keyframe_time: 0,
+
+        let rotated = match self.writer.append(&record) {
+            Ok(rotated) => rotated,
+            Err(e) => {
+                error!("Failed to write recording of session {}: {}", self.metadata.session_id, e);
+                self.stats.lock().recording_errors += 1;
+                return;
+            }
         };
-        
-        *self.video_writer.write().await = Some(writer);
-        
-        // Update metadata
+
         {
-            let mut metadata = self.metadata.write().await;
-            metadata.files.video_file = Some(video_path);
+            let mut stats = self.stats.lock();
+            match &record {
+                Record::Frame(_) => stats.frames_recorded += 1,
+                Record::Event { entry: TimelineEntry::Input { .. }, .. } => stats.input_events_recorded += 1,
+                Record::Event { .. } => stats.timeline_events_recorded += 1,
+            }
+            stats.bytes_written = self.writer.total_bytes();
         }
-        
-        info!("Video recording writer initialized");
-        Ok(())
-    }
-    
-    /// Initialize input recording writer
-    async fn init_input_writer(&self) -> Result<()> {
-        let input_path = self.recording_path.with_extension("input");
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&input_path)
-            .map_err(|e| GhostLinkError::Other(format!("Failed to create input file: {}", e)))?;
-        
-        let writer = InputRecordingWriter {
-            file: BufWriter::new(file),
-            event_count: 0,
-        };
-        
-        *self.input_writer.write().await = Some(writer);
-        
-        // Update metadata
-        {
-            let mut metadata = self.metadata.write().await;
-            metadata.files.input_file = Some(input_path);
+
+        let config = &self.metadata.config;
+        if config.max_size_bytes.is_some_and(|max| self.writer.total_bytes() >= max) {
+            self.halt("Recording size limit reached");
+        } else if rotated {
+            debug!("Recording of session {} rotated to segment {}", self.metadata.session_id, self.writer.segments().len());
+            let low_space = available_space(&config.recording_directory)
+                .is_some_and(|available| available < config.min_free_space_bytes);
+            if low_space {
+                self.halt("Recording stopped: low disk space");
+            }
+            self.checkpoint();
         }
-        
-        info!("Input recording writer initialized");
-        Ok(())
     }
-    
-    /// Close all writers
-    async fn close_writers(&self) -> Result<()> {
-        if let Some(mut writer) = self.video_writer.write().await.take() {
-            writer.file.flush()?;
-            info!("Video writer closed, {} frames recorded", writer.frame_count);
+
+    /// Stop accepting records but keep what was written
+    fn halt(&mut self, reason: &str) {
+        self.is_recording.store(false, Ordering::Relaxed);
+        if !self.halted {
+            self.halted = true;
+            warn!("{} for session {}", reason, self.metadata.session_id);
+            self.push_audit(unix_micros(), "recording", reason);
         }
-        
-        if let Some(mut writer) = self.input_writer.write().await.take() {
-            writer.file.flush()?;
-            info!("Input writer closed, {} events recorded", writer.event_count);
+    }
+
+    /// Mirror audit-relevant timeline entries into the compliance log
+    fn audit(&mut self, timestamp_us: u64, entry: &TimelineEntry) {
+        match entry {
+            TimelineEntry::Session { description, .. } => self.push_audit(timestamp_us, "session", description),
+            TimelineEntry::Command { command, .. } => {
+                self.push_audit(timestamp_us, "command", &format!("Executed: {}", command))
+            }
+            _ => {}
         }
-        
-        Ok(())
     }
-    
-    /// Update final metadata before saving
-    async fn update_final_metadata(&self) -> Result<()> {
-        let mut metadata = self.metadata.write().await;
-        
-        if let Some(start_time) = *self.start_time.read().await {
-            let duration = start_time.elapsed().unwrap_or_default();
-            metadata.duration_seconds = duration.as_secs_f64();
-            metadata.end_time = Some(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs()
-            );
+
+    fn push_audit(&mut self, timestamp_us: u64, event_type: &str, description: &str) {
+        self.metadata.compliance.audit_log.push(AuditLogEntry {
+            timestamp: timestamp_us,
+            event_type: event_type.to_string(),
+            description: description.to_string(),
+            operator_id: self.metadata.operator.user_id.clone(),
+            client_ip: self.metadata.operator.client_ip.clone(),
+        });
+    }
+
+    /// Flush segments and rewrite the index
+    fn checkpoint(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Failed to flush recording of session {}: {}", self.metadata.session_id, e);
+            self.stats.lock().recording_errors += 1;
         }
-        
-        metadata.stats = self.stats.read().clone();
-        
-        // Calculate compression ratio
-        if metadata.stats.bytes_written > 0 {
-            // Estimate raw frame size and calculate compression
-            let estimated_raw_size = metadata.stats.frames_recorded * 1920 * 1080 * 4; // Assume 1080p RGBA
-            metadata.stats.compression_ratio = estimated_raw_size as f64 / metadata.stats.bytes_written as f64;
+
+        self.metadata.files.segments = self.writer.segments().to_vec();
+        self.metadata.duration_seconds = self.started.elapsed().as_secs_f64();
+        self.metadata.stats = self.stats.lock().clone();
+        if self.metadata.duration_seconds > 0.0 {
+            self.metadata.stats.average_fps = self.metadata.stats.frames_recorded as f64 / self.metadata.duration_seconds;
         }
-        
-        // Calculate average FPS
-        if metadata.duration_seconds > 0.0 {
-            metadata.stats.average_fps = metadata.stats.frames_recorded as f64 / metadata.duration_seconds;
+
+        if let Err(e) = save_metadata(&self.metadata) {
+            error!("Failed to save recording index of session {}: {}", self.metadata.session_id, e);
         }
-        
-        info!("Final recording metadata updated: {:.1}s, {} frames, {} events",
-            metadata.duration_seconds,
-            metadata.stats.frames_recorded,
-            metadata.stats.input_events_recorded
-        );
-        
-        Ok(())
-    }
-    
-    /// Save recording metadata to file
-    async fn save_metadata(&self) -> Result<()> {
-        let metadata = self.metadata.read().await;
-        let json = serde_json::to_string_pretty(&*metadata)
-            .map_err(|e| GhostLinkError::Other(format!("Failed to serialize metadata: {}", e)))?;
-        
-        std::fs::write(&metadata.files.metadata_file, json)
-            .map_err(|e| GhostLinkError::Other(format!("Failed to write metadata file: {}", e)))?;
-        
-        info!("Recording metadata saved to: {:?}", metadata.files.metadata_file);
-        Ok(())
     }
-    
-    /// Gather system information for metadata
-    async fn gather_system_info() -> SystemInfo {
-        use sysinfo::System;
-        
-        let mut sys = System::new_all();
-        sys.refresh_all();
-        
-        SystemInfo {
-            device_name: System::host_name().unwrap_or_else(|| "Unknown".to_string()),
-            operating_system: System::name().unwrap_or_else(|| "Unknown".to_string()),
-            system_version: System::os_version().unwrap_or_else(|| "Unknown".to_string()),
-            cpu_info: sys.cpus().first()
-                .map(|cpu| format!("{} ({} cores)", cpu.brand(), sys.cpus().len()))
-                .unwrap_or_else(|| "Unknown".to_string()),
-            memory_info: format!("{} MB total, {} MB available", 
-                sys.total_memory() / 1024 / 1024,
-                sys.available_memory() / 1024 / 1024),
-            network_interfaces: vec![], // TODO: Implement network interface detection
-            screen_resolution: "1920x1080".to_string(), // TODO: Get actual resolution
-            agent_version: env!("CARGO_PKG_VERSION").to_string(),
-            system_uptime: System::uptime(),
+
+    fn finish(&mut self) {
+        self.is_recording.store(false, Ordering::Relaxed);
+        // Records queued before the stop still belong to the recording
+        while let Ok(command) = self.rx.try_recv() {
+            if let WriterCommand::Record(record, _lease) = command {
+                self.write(record);
+            }
         }
+        self.metadata.end_time = Some(unix_seconds());
+        self.checkpoint();
+        info!("Recording finalized: {:.1}s, {} frames, {} input events",
+            self.metadata.duration_seconds,
+            self.metadata.stats.frames_recorded,
+            self.metadata.stats.input_events_recorded
+        );
     }
-    
-    /// Get recording statistics
-    pub fn get_stats(&self) -> RecordingStats {
-        self.stats.read().clone()
-    }
-    
-    /// Check if currently recording
-    pub fn is_recording(&self) -> bool {
-        self.is_recording.load(Ordering::Relaxed)
-    }
-    
-    /// Get recording metadata
-    pub async fn get_metadata(&self) -> RecordingMetadata {
-        self.metadata.read().await.clone()
-    }
-    
-    /// Get recording file path
-    pub fn get_recording_path(&self) -> &PathBuf {
-        &self.recording_path
+}
+
+/// Write the sidecar atomically, so a crash never leaves half an index
+fn save_metadata(metadata: &RecordingMetadata) -> Result<()> {
+    let json = serde_json::to_vec_pretty(metadata)?;
+    let path = &metadata.files.metadata_file;
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, json)
+        .and_then(|_| std::fs::rename(&temp, path))
+        .map_err(|e| GhostLinkError::Other(format!("Failed to write metadata file: {}", e)))
+}
+
+/// Read a recording's JSON sidecar
+pub fn load_metadata(path: &Path) -> Result<RecordingMetadata> {
+    let json = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Free space on the disk holding `path`, if it can be determined
+fn available_space(path: &Path) -> Option<u64> {
+    use sysinfo::Disks;
+
+    let path = path.canonicalize().ok()?;
+    Disks::new_with_refreshed_list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// Keep session IDs from escaping the recording directory
+fn sanitize(session_id: &str) -> String {
+    session_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn unix_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/// Gather system information for metadata
+fn gather_system_info() -> SystemInfo {
+    use sysinfo::System;
+
+    let mut sys = System::new();
+    sys.refresh_cpu();
+    sys.refresh_memory();
+
+    SystemInfo {
+        device_name: System::host_name().unwrap_or_else(|| "Unknown".to_string()),
+        operating_system: System::name().unwrap_or_else(|| "Unknown".to_string()),
+        system_version: System::os_version().unwrap_or_else(|| "Unknown".to_string()),
+        cpu_info: sys.cpus().first()
+            .map(|cpu| format!("{} ({} cores)", cpu.brand(), sys.cpus().len()))
+            .unwrap_or_else(|| "Unknown".to_string()),
+        memory_info: format!("{} MB total, {} MB available",
+            sys.total_memory() / 1024 / 1024,
+            sys.available_memory() / 1024 / 1024),
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        system_uptime: System::uptime(),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
-    #[tokio::test]
-    async fn test_session_recorder_creation() {
-        let config = RecordingConfig::default();
-        let recorder = SessionRecorder::new("test_session".to_string(), config).await.unwrap();
-        
-        assert_eq!(recorder.session_id, "test_session");
-        assert!(!recorder.is_recording());
+    use crate::recording::container::RecordingReader;
+    use tempfile::TempDir;
+
+    fn config(dir: &Path) -> RecordingConfig {
+        RecordingConfig {
+            recording_directory: dir.to_path_buf(),
+            min_free_space_bytes: 0,
+            ..Default::default()
+        }
     }
-    
+
     #[tokio::test]
     async fn test_recording_lifecycle() {
-        let mut config = RecordingConfig::default();
-        config.recording_directory = PathBuf::from("./test_recordings");
-        
-        let recorder = SessionRecorder::new("test_session".to_string(), config).await.unwrap();
-        
-        let operator_info = OperatorInfo {
-            user_id: "test_user".to_string(),
-            display_name: "Test User".to_string(),
-            email: "test@example.com".to_string(),
-            organization: "Test Org".to_string(),
-            auth_method: "test".to_string(),
-            client_ip: "127.0.0.1".to_string(),
-            user_agent: "test".to_string(),
-        };
-        
-        // Start recording
-        recorder.start_recording(operator_info).await.unwrap();
+        let dir = TempDir::new().unwrap();
+        let recorder = SessionRecorder::start("test/session", config(dir.path()), OperatorInfo::from_requester("tech")).unwrap();
         assert!(recorder.is_recording());
-        
-        // Stop recording
-        recorder.stop_recording().await.unwrap();
+        // Session IDs never become paths
+        assert_eq!(recorder.metadata_path().parent().unwrap(), dir.path());
+
+        recorder.record_frame(1280, 720, true, &[1, 2, 3]);
+        recorder.record_input("mouse_move", &serde_json::json!({"x": 10, "y": 20}));
+        recorder.record_command("whoami", Some(0));
+        recorder.record_chat("tech", "Restarting the print spooler");
+
+        let metadata = recorder.stop().await.unwrap();
         assert!(!recorder.is_recording());
-        
-        // Cleanup test files
-        let _ = std::fs::remove_dir_all("./test_recordings");
+        assert!(metadata.end_time.is_some());
+        assert_eq!(metadata.stats.frames_recorded, 1);
+        assert_eq!(metadata.stats.input_events_recorded, 1);
+        let audit: Vec<&str> = metadata.compliance.audit_log.iter().map(|e| e.event_type.as_str()).collect();
+        assert_eq!(audit, vec!["session", "command", "session"]);
+
+        // The sidecar on disk matches and the records play back in order
+        let saved = load_metadata(recorder.metadata_path()).unwrap();
+        assert_eq!(saved.files.segments, metadata.files.segments);
+        let records: Vec<Record> = RecordingReader::new(dir.path(), &saved.files.segments)
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(matches!(&records[1], Record::Frame(frame) if frame.keyframe && frame.data == vec![1, 2, 3]));
+        assert!(matches!(&records[4], Record::Event { entry: TimelineEntry::Chat { .. }, .. }));
+
+        // Nothing is accepted after stopping
+        recorder.record_frame(1280, 720, false, &[4]);
+        assert_eq!(recorder.get_stats().frames_dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn test_size_limit_stops_recording() {
+        let dir = TempDir::new().unwrap();
+        let config = RecordingConfig {
+            segment_size_bytes: 4096,
+            max_size_bytes: Some(10_000),
+            ..config(dir.path())
+        };
+        let recorder = SessionRecorder::start("limited", config, OperatorInfo::from_requester("tech")).unwrap();
+        for _ in 0..8 {
+            recorder.record_frame(640, 480, false, &[0; 2000]);
+        }
+
+        let metadata = recorder.stop().await.unwrap();
+        assert!(metadata.stats.bytes_written >= 10_000);
+        assert!(metadata.stats.frames_recorded < 8);
+        assert!(metadata.files.segments.len() > 1);
+        assert!(metadata.compliance.audit_log.iter().any(|e| e.description.contains("size limit")));
     }
-}
\ No newline at end of file
+}
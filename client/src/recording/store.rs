@@ -0,0 +1,137 @@
+//! Finding and exporting recordings in the recording directory
+
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use super::container::{segment_file_name, SegmentInfo};
+use super::session_recorder::{load_metadata, RecordingMetadata, METADATA_SUFFIX};
+
+/// All recordings in `directory`, oldest first
+pub fn list_recordings(directory: &Path) -> Result<Vec<RecordingMetadata>> {
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut recordings = Vec::new();
+    for entry in fs::read_dir(directory)
+        .with_context(|| format!("Failed to read {}", directory.display()))?
+    {
+        let path = entry?.path();
+        let is_sidecar = path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(METADATA_SUFFIX));
+        if !is_sidecar {
+            continue;
+        }
+        match load_metadata(&path) {
+            Ok(metadata) => recordings.push(metadata),
+            Err(e) => warn!("Skipping unreadable recording index {}: {}", path.display(), e),
+        }
+    }
+
+    recordings.sort_by(|a, b| a.start_time.cmp(&b.start_time).then_with(|| a.recording_id.cmp(&b.recording_id)));
+    Ok(recordings)
+}
+
+/// Find a recording by recording ID or session ID
+pub fn find_recording(directory: &Path, id: &str) -> Result<RecordingMetadata> {
+    let mut matches: Vec<_> = list_recordings(directory)?
+        .into_iter()
+        .filter(|r| r.recording_id == id || r.session_id == id)
+        .collect();
+    match matches.len() {
+        0 => bail!("No recording {} in {}", id, directory.display()),
+        // A session ID may have been recorded more than once; take the latest
+        _ => Ok(matches.pop().unwrap()),
+    }
+}
+
+/// Segments of a recording, including any started after the index was last
+/// written (a recording whose agent died before its next flush)
+pub fn segment_files(directory: &Path, metadata: &RecordingMetadata) -> Vec<SegmentInfo> {
+    let mut segments = metadata.files.segments.clone();
+    loop {
+        let file_name = segment_file_name(&metadata.recording_id, segments.len());
+        if !directory.join(&file_name).exists() {
+            return segments;
+        }
+        segments.push(SegmentInfo {
+            file_name,
+            ..Default::default()
+        });
+    }
+}
+
+/// Bundle a recording's index and segments into a tar archive.
+///
+/// Returns the path written; by default `<recording_id>.tar` in the current
+/// directory.
+pub fn export_recording(directory: &Path, id: &str, output: Option<&Path>) -> Result<PathBuf> {
+    let metadata = find_recording(directory, id)?;
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from(format!("{}.tar", metadata.recording_id)));
+
+    let file = File::create(&output)
+        .with_context(|| format!("Failed to create {}", output.display()))?;
+    let mut builder = tar::Builder::new(file);
+
+    let sidecar = format!("{}{}", metadata.recording_id, METADATA_SUFFIX);
+    builder.append_path_with_name(directory.join(&sidecar), &sidecar)?;
+    for segment in segment_files(directory, &metadata) {
+        builder.append_path_with_name(directory.join(&segment.file_name), &segment.file_name)
+            .with_context(|| format!("Missing segment {}", segment.file_name))?;
+    }
+    builder.into_inner()?.sync_all()?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::session_recorder::{OperatorInfo, RecordingConfig, SessionRecorder};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_list_and_export() {
+        let dir = TempDir::new().unwrap();
+        let config = RecordingConfig {
+            recording_directory: dir.path().to_path_buf(),
+            min_free_space_bytes: 0,
+            ..Default::default()
+        };
+        let recorder = SessionRecorder::start("abc", config, OperatorInfo::from_requester("tech")).unwrap();
+        recorder.record_frame(800, 600, true, &[9; 32]);
+        let metadata = recorder.stop().await.unwrap();
+
+        let recordings = list_recordings(dir.path()).unwrap();
+        assert_eq!(recordings.len(), 1);
+        assert_eq!(recordings[0].recording_id, metadata.recording_id);
+        assert!(find_recording(dir.path(), "abc").is_ok());
+        assert!(find_recording(dir.path(), "missing").is_err());
+
+        let output = dir.path().join("export.tar");
+        export_recording(dir.path(), &metadata.recording_id, Some(&output)).unwrap();
+        let mut archive = tar::Archive::new(File::open(&output).unwrap());
+        let mut names: Vec<String> = archive.entries().unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        let mut expected = vec![format!("{}.json", metadata.recording_id)];
+        expected.extend(metadata.files.segments.iter().map(|s| s.file_name.clone()));
+        expected.sort();
+        assert_eq!(names, expected);
+
+        // The export landed in the recording directory but is not a recording
+        assert_eq!(list_recordings(dir.path()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_missing_directory_is_empty() {
+        let dir = TempDir::new().unwrap();
+        assert!(list_recordings(&dir.path().join("nope")).unwrap().is_empty());
+    }
+}
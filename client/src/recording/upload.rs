@@ -0,0 +1,275 @@
+//! Uploading recordings to the server
+//!
+//! The segment files of a recording are sent as one byte stream cut into
+//! `CHUNK_SIZE` chunks. The declaration (`POST /api/recordings`) lists each
+//! chunk's size and SHA-256, and the server answers with the chunks it is
+//! still missing, so running the upload again resumes it. While a recording
+//! is still being written only whole chunks of its closed segments are
+//! declared; once it has stopped the rest follows and the upload is final.
+//! Every request is signed with the enrollment key.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use url::Url;
+use uuid::Uuid;
+
+use super::session_recorder::RecordingMetadata;
+use super::store::segment_files;
+use crate::config::ClientConfig;
+use crate::logging::bundle::{SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+/// Bytes per uploaded chunk
+pub const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Content type the server streams uploaded recordings back with
+pub const CONTENT_TYPE: &str = "application/vnd.ghostlink.recording";
+
+/// The server's answer to every upload request
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadState {
+    pub id: Uuid,
+    /// `uploading` or `complete`
+    pub status: String,
+    /// Declared chunks the server does not have yet
+    pub missing: Vec<u32>,
+}
+
+/// What one run of the upload did
+#[derive(Debug, Clone)]
+pub struct UploadSummary {
+    pub state: UploadState,
+    pub chunks_sent: usize,
+    pub bytes_sent: u64,
+}
+
+/// The part of a recording that can be uploaded now
+#[derive(Debug, Clone, PartialEq)]
+struct UploadPlan {
+    /// Segment files in order, with their sizes
+    files: Vec<(PathBuf, u64)>,
+    /// Bytes to upload, a prefix of the files
+    length: u64,
+    /// The recording stopped, so these are all of its bytes
+    finished: bool,
+}
+
+impl UploadPlan {
+    fn new(directory: &Path, metadata: &RecordingMetadata) -> Result<Self> {
+        let finished = metadata.end_time.is_some();
+        let mut segments = segment_files(directory, metadata);
+        if !finished {
+            // The recorder is still appending to its last segment
+            segments.pop();
+        }
+        let mut files = Vec::new();
+        for segment in segments {
+            let path = directory.join(&segment.file_name);
+            let size = path.metadata().with_context(|| format!("Missing segment {}", segment.file_name))?.len();
+            files.push((path, size));
+        }
+
+        let total: u64 = files.iter().map(|(_, size)| size).sum();
+        let length = if finished { total } else { total - total % CHUNK_SIZE };
+        Ok(Self { files, length, finished })
+    }
+
+    fn chunk_count(&self) -> u32 {
+        self.length.div_ceil(CHUNK_SIZE) as u32
+    }
+
+    /// Bytes of chunk `index`, read across segment boundaries
+    fn read_chunk(&self, index: u32) -> Result<Vec<u8>> {
+        let start = index as u64 * CHUNK_SIZE;
+        let end = (start + CHUNK_SIZE).min(self.length);
+        let mut chunk = Vec::with_capacity((end - start) as usize);
+        let mut offset = 0;
+        for (path, size) in &self.files {
+            let (file_start, file_end) = (offset, offset + size);
+            offset = file_end;
+            if file_end <= start || file_start >= end {
+                continue;
+            }
+            let from = start.max(file_start) - file_start;
+            let to = end.min(file_end) - file_start;
+            let mut file = File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            file.seek(SeekFrom::Start(from))?;
+            file.take(to - from).read_to_end(&mut chunk)?;
+        }
+        if chunk.len() as u64 != end - start {
+            bail!("Recording segments changed while they were read");
+        }
+        Ok(chunk)
+    }
+}
+
+/// Endpoint under `/api/recordings` on the relay's host
+fn recordings_url(server_url: &str, path: &str) -> Result<Url> {
+    let mut url = Url::parse(server_url).context("Invalid server URL")?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        other => bail!("Unsupported server URL scheme: {}", other),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow::anyhow!("Cannot convert {} to an HTTP URL", server_url))?;
+    url.set_path(&format!("/api/recordings{}", path));
+    url.set_query(None);
+    Ok(url)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(digest(&SHA256, data))
+}
+
+fn timestamp(unix_seconds: u64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(unix_seconds as i64, 0)
+}
+
+/// Send a signed request and read the upload state from the answer
+async fn send(config: &ClientConfig, request: reqwest::RequestBuilder, body: Vec<u8>) -> Result<UploadState> {
+    let Some(credential) = &config.credential else {
+        bail!("Device is not enrolled; uploads are signed with the enrollment key");
+    };
+    let timestamp = Utc::now().timestamp();
+    let signature = credential.sign_recording(&config.agent_id, timestamp, &sha256_hex(&body))?;
+    let response = request
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, signature)
+        .body(body)
+        .send()
+        .await
+        .context("Failed to reach the server")?;
+
+    let status = response.status();
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    if !status.is_success() {
+        let reason = body.get("error").and_then(|e| e.as_str()).unwrap_or("no details");
+        bail!("Upload rejected ({}): {}", status, reason);
+    }
+    serde_json::from_value(body).context("Unexpected answer to the upload")
+}
+
+/// Upload what the server is missing of a recording
+pub async fn upload_recording(config: &ClientConfig, directory: &Path, metadata: &RecordingMetadata) -> Result<UploadSummary> {
+    if config.credential.is_none() {
+        bail!("Device is not enrolled; uploads are signed with the enrollment key");
+    }
+    let session_id: Uuid = metadata.session_id.parse()
+        .with_context(|| format!("Session {} has no server ID to file the recording under", metadata.session_id))?;
+    let plan = UploadPlan::new(directory, metadata)?;
+
+    let mut chunks = Vec::new();
+    for index in 0..plan.chunk_count() {
+        let data = plan.read_chunk(index)?;
+        chunks.push(serde_json::json!({ "index": index, "size": data.len(), "sha256": sha256_hex(&data) }));
+    }
+    let declaration = serde_json::json!({
+        "agent_id": config.agent_id,
+        "session_id": session_id,
+        "source_id": metadata.recording_id,
+        "started_at": timestamp(metadata.start_time),
+        "ended_at": metadata.end_time.and_then(timestamp),
+        "content_type": CONTENT_TYPE,
+        "metadata": metadata,
+        "chunks": chunks,
+        "final": plan.finished,
+    });
+
+    let client = crate::connection::proxy::http_client();
+    let request = client.post(recordings_url(&config.server_url, "")?)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    let mut state = send(config, request, serde_json::to_vec(&declaration)?).await?;
+
+    let (mut chunks_sent, mut bytes_sent) = (0, 0);
+    for index in state.missing.clone() {
+        let data = plan.read_chunk(index)?;
+        let url = recordings_url(&config.server_url, &format!("/{}/chunks/{}", state.id, index))?;
+        let request = client.put(url).header(reqwest::header::CONTENT_TYPE, "application/octet-stream");
+        bytes_sent += data.len() as u64;
+        state = send(config, request, data).await?;
+        chunks_sent += 1;
+    }
+    Ok(UploadSummary { state, chunks_sent, bytes_sent })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::container::SegmentInfo;
+    use crate::recording::session_recorder::{OperatorInfo, RecordingConfig, SessionRecorder};
+    use tempfile::TempDir;
+
+    fn plan_of(dir: &Path, sizes: &[u64], finished: bool) -> UploadPlan {
+        let mut files = Vec::new();
+        for (i, size) in sizes.iter().enumerate() {
+            let path = dir.join(format!("segment-{}", i));
+            std::fs::write(&path, vec![i as u8; *size as usize]).unwrap();
+            files.push((path, *size));
+        }
+        let total: u64 = sizes.iter().sum();
+        UploadPlan { files, length: if finished { total } else { total - total % CHUNK_SIZE }, finished }
+    }
+
+    #[test]
+    fn test_chunks_span_segments() {
+        let dir = TempDir::new().unwrap();
+        let plan = plan_of(dir.path(), &[CHUNK_SIZE / 2, CHUNK_SIZE, 10], true);
+        assert_eq!(plan.chunk_count(), 2);
+
+        let first = plan.read_chunk(0).unwrap();
+        assert_eq!(first.len() as u64, CHUNK_SIZE);
+        assert_eq!(first[0], 0);
+        assert_eq!(first[CHUNK_SIZE as usize / 2], 1);
+        let last = plan.read_chunk(1).unwrap();
+        assert_eq!(last.len() as u64, CHUNK_SIZE / 2 + 10);
+        assert_eq!(last[last.len() - 1], 2);
+    }
+
+    #[test]
+    fn test_unfinished_recordings_declare_whole_chunks_only() {
+        let dir = TempDir::new().unwrap();
+        let plan = plan_of(dir.path(), &[CHUNK_SIZE + 5], false);
+        assert_eq!(plan.chunk_count(), 1);
+        assert_eq!(plan.read_chunk(0).unwrap().len() as u64, CHUNK_SIZE);
+        assert_eq!(plan_of(dir.path(), &[CHUNK_SIZE - 1], false).chunk_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_plan_leaves_out_the_segment_being_written() {
+        let dir = TempDir::new().unwrap();
+        let config = RecordingConfig {
+            recording_directory: dir.path().to_path_buf(),
+            min_free_space_bytes: 0,
+            ..Default::default()
+        };
+        let recorder = SessionRecorder::start(&Uuid::new_v4().to_string(), config, OperatorInfo::from_requester("tech")).unwrap();
+        recorder.record_frame(800, 600, true, &[9; 32]);
+        let mut metadata = recorder.stop().await.unwrap();
+
+        let plan = UploadPlan::new(dir.path(), &metadata).unwrap();
+        assert!(plan.finished);
+        assert_eq!(plan.files.len(), metadata.files.segments.len());
+        assert_eq!(plan.chunk_count(), 1);
+        assert!(plan.read_chunk(0).unwrap().starts_with(b"GLR1"));
+
+        metadata.end_time = None;
+        metadata.files.segments.push(SegmentInfo::default());
+        let plan = UploadPlan::new(dir.path(), &metadata).unwrap();
+        assert!(!plan.finished);
+        assert_eq!(plan.chunk_count(), 0);
+    }
+
+    #[test]
+    fn test_recordings_url() {
+        assert_eq!(
+            recordings_url("wss://relay.example.com/relay/ws", "/abc/chunks/3").unwrap().as_str(),
+            "https://relay.example.com/api/recordings/abc/chunks/3"
+        );
+        assert_eq!(recordings_url("ws://localhost:8443", "").unwrap().as_str(), "http://localhost:8443/api/recordings");
+    }
+}
@@ -0,0 +1,752 @@
+//! Remote registry editing for Windows agents
+//!
+//! Every operation is checked against the agent's [`RegistryPolicy`] allowlist
+//! and recorded in an audit log with the affected data before and after the
+//! change, rendered in `.reg` syntax.
+
+#![allow(dead_code)]
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+#[cfg(windows)]
+mod windows;
+
+/// Header line of exported `.reg` files
+pub const REG_FILE_HEADER: &str = "Windows Registry Editor Version 5.00";
+
+/// Number of audit entries kept in memory
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+// Raw registry value type identifiers
+const REG_SZ: u32 = 1;
+const REG_EXPAND_SZ: u32 = 2;
+const REG_BINARY: u32 = 3;
+const REG_DWORD: u32 = 4;
+const REG_MULTI_SZ: u32 = 7;
+const REG_QWORD: u32 = 11;
+
+/// Top-level registry hives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RegistryHive {
+    ClassesRoot,
+    CurrentUser,
+    LocalMachine,
+    Users,
+    CurrentConfig,
+}
+
+impl RegistryHive {
+    /// Full hive name as used by regedit
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegistryHive::ClassesRoot => "HKEY_CLASSES_ROOT",
+            RegistryHive::CurrentUser => "HKEY_CURRENT_USER",
+            RegistryHive::LocalMachine => "HKEY_LOCAL_MACHINE",
+            RegistryHive::Users => "HKEY_USERS",
+            RegistryHive::CurrentConfig => "HKEY_CURRENT_CONFIG",
+        }
+    }
+
+    /// Whether writes under this hive need administrative rights
+    pub fn requires_admin(&self) -> bool {
+        !matches!(self, RegistryHive::CurrentUser)
+    }
+}
+
+impl std::str::FromStr for RegistryHive {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "HKCR" | "HKEY_CLASSES_ROOT" => Ok(RegistryHive::ClassesRoot),
+            "HKCU" | "HKEY_CURRENT_USER" => Ok(RegistryHive::CurrentUser),
+            "HKLM" | "HKEY_LOCAL_MACHINE" => Ok(RegistryHive::LocalMachine),
+            "HKU" | "HKEY_USERS" => Ok(RegistryHive::Users),
+            "HKCC" | "HKEY_CURRENT_CONFIG" => Ok(RegistryHive::CurrentConfig),
+            other => Err(anyhow::anyhow!("Unknown registry hive: {}", other)),
+        }
+    }
+}
+
+/// A normalized registry key path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryPath {
+    pub hive: RegistryHive,
+    /// Path below the hive, backslash separated; empty for the hive itself
+    pub subkey: String,
+}
+
+impl RegistryPath {
+    /// Parse a path such as `HKLM\SOFTWARE\Vendor` or `HKEY_CURRENT_USER/Software`
+    pub fn parse(path: &str) -> Result<Self> {
+        let normalized = path.trim().replace('/', "\\");
+        let mut components = normalized.split('\\').filter(|c| !c.is_empty());
+
+        let hive = components.next()
+            .ok_or_else(|| anyhow::anyhow!("Empty registry path"))?
+            .parse::<RegistryHive>()?;
+        let subkey = components.collect::<Vec<_>>().join("\\");
+
+        Ok(Self { hive, subkey })
+    }
+
+    /// Path of a direct child key
+    pub fn child(&self, name: &str) -> Self {
+        let subkey = if self.subkey.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}\\{}", self.subkey, name)
+        };
+        Self { hive: self.hive, subkey }
+    }
+
+    /// Whether this path is `other` or lies below it, ignoring case
+    pub fn is_within(&self, other: &RegistryPath) -> bool {
+        if self.hive != other.hive {
+            return false;
+        }
+        if other.subkey.is_empty() {
+            return true;
+        }
+
+        let own = self.subkey.to_lowercase();
+        let prefix = other.subkey.to_lowercase();
+        own == prefix || own.starts_with(&format!("{}\\", prefix))
+    }
+}
+
+impl std::fmt::Display for RegistryPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.subkey.is_empty() {
+            write!(f, "{}", self.hive.name())
+        } else {
+            write!(f, "{}\\{}", self.hive.name(), self.subkey)
+        }
+    }
+}
+
+/// Typed registry value data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum RegistryData {
+    String(String),
+    ExpandString(String),
+    MultiString(Vec<String>),
+    Dword(u32),
+    Qword(u64),
+    Binary(Vec<u8>),
+    /// Any other value type, kept verbatim
+    Raw { kind: u32, bytes: Vec<u8> },
+}
+
+impl RegistryData {
+    /// Raw registry type identifier (`REG_SZ`, `REG_DWORD`, ...)
+    pub fn raw_type(&self) -> u32 {
+        match self {
+            RegistryData::String(_) => REG_SZ,
+            RegistryData::ExpandString(_) => REG_EXPAND_SZ,
+            RegistryData::MultiString(_) => REG_MULTI_SZ,
+            RegistryData::Dword(_) => REG_DWORD,
+            RegistryData::Qword(_) => REG_QWORD,
+            RegistryData::Binary(_) => REG_BINARY,
+            RegistryData::Raw { kind, .. } => *kind,
+        }
+    }
+
+    /// Encode the data the way the registry stores it
+    pub fn to_raw_bytes(&self) -> Vec<u8> {
+        match self {
+            RegistryData::String(s) | RegistryData::ExpandString(s) => utf16_bytes(s),
+            RegistryData::MultiString(items) => {
+                let mut bytes = Vec::new();
+                for item in items {
+                    bytes.extend(utf16_bytes(item));
+                }
+                bytes.extend_from_slice(&[0, 0]);
+                bytes
+            }
+            RegistryData::Dword(v) => v.to_le_bytes().to_vec(),
+            RegistryData::Qword(v) => v.to_le_bytes().to_vec(),
+            RegistryData::Binary(bytes) | RegistryData::Raw { bytes, .. } => bytes.clone(),
+        }
+    }
+
+    /// Decode data as read from the registry
+    pub fn from_raw(kind: u32, bytes: &[u8]) -> Self {
+        match kind {
+            REG_SZ => RegistryData::String(utf16_string(bytes)),
+            REG_EXPAND_SZ => RegistryData::ExpandString(utf16_string(bytes)),
+            REG_MULTI_SZ => {
+                let items = utf16_units(bytes)
+                    .split(|unit| *unit == 0)
+                    .filter(|item| !item.is_empty())
+                    .map(String::from_utf16_lossy)
+                    .collect();
+                RegistryData::MultiString(items)
+            }
+            REG_DWORD if bytes.len() >= 4 => {
+                RegistryData::Dword(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+            REG_QWORD if bytes.len() >= 8 => {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[..8]);
+                RegistryData::Qword(u64::from_le_bytes(buf))
+            }
+            REG_BINARY => RegistryData::Binary(bytes.to_vec()),
+            kind => RegistryData::Raw { kind, bytes: bytes.to_vec() },
+        }
+    }
+}
+
+fn utf16_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+fn utf16_units(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+fn utf16_string(bytes: &[u8]) -> String {
+    let units = utf16_units(bytes);
+    let end = units.iter().position(|unit| *unit == 0).unwrap_or(units.len());
+    String::from_utf16_lossy(&units[..end])
+}
+
+/// A named value within a key; the default value has an empty name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegistryValue {
+    pub name: String,
+    pub data: RegistryData,
+}
+
+/// Result of querying a key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryKeyInfo {
+    pub path: String,
+    pub subkeys: Vec<String>,
+    pub values: Vec<RegistryValue>,
+}
+
+/// Access to the underlying registry, mocked in tests
+pub trait RegistryBackend: Send + Sync {
+    fn key_exists(&self, path: &RegistryPath) -> Result<bool>;
+    fn list_subkeys(&self, path: &RegistryPath) -> Result<Vec<String>>;
+    fn list_values(&self, path: &RegistryPath) -> Result<Vec<RegistryValue>>;
+    fn set_value(&self, path: &RegistryPath, name: &str, data: &RegistryData) -> Result<()>;
+    fn delete_value(&self, path: &RegistryPath, name: &str) -> Result<()>;
+    fn create_key(&self, path: &RegistryPath) -> Result<()>;
+    /// Delete a key together with all of its subkeys
+    fn delete_key(&self, path: &RegistryPath) -> Result<()>;
+
+    fn get_value(&self, path: &RegistryPath, name: &str) -> Result<Option<RegistryData>> {
+        Ok(self.list_values(path)?
+            .into_iter()
+            .find(|value| value.name.eq_ignore_ascii_case(name))
+            .map(|value| value.data))
+    }
+}
+
+/// The registry of the machine the agent runs on, if it has one
+pub fn platform_backend() -> Option<Box<dyn RegistryBackend>> {
+    #[cfg(windows)]
+    {
+        Some(Box::new(windows::WindowsRegistry))
+    }
+
+    #[cfg(not(windows))]
+    {
+        None
+    }
+}
+
+/// Policy limiting which keys a technician may read or change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryPolicy {
+    /// Master switch for registry operations
+    pub enabled: bool,
+    /// Keys (and everything below them) that may be accessed
+    pub allowed_paths: Vec<String>,
+}
+
+impl Default for RegistryPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allowed_paths: vec![
+                "HKEY_CURRENT_USER\\Software".to_string(),
+                "HKEY_LOCAL_MACHINE\\SOFTWARE".to_string(),
+                "HKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Services".to_string(),
+            ],
+        }
+    }
+}
+
+impl RegistryPolicy {
+    /// Check that `path` may be accessed under this policy
+    pub fn check_path(&self, path: &RegistryPath) -> Result<()> {
+        if !self.enabled {
+            return Err(anyhow::anyhow!("Registry editing is disabled by policy"));
+        }
+
+        let allowed = self.allowed_paths.iter()
+            .filter_map(|allowed| RegistryPath::parse(allowed).ok())
+            .any(|allowed| path.is_within(&allowed));
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Registry path {} is not allowed by policy", path))
+        }
+    }
+}
+
+/// Registry command sent by the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum RegistryOperation {
+    Query { path: String },
+    SetValue { path: String, name: String, value: RegistryData },
+    DeleteValue { path: String, name: String },
+    CreateKey { path: String },
+    DeleteKey { path: String },
+    Export { path: String },
+}
+
+impl RegistryOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegistryOperation::Query { .. } => "query",
+            RegistryOperation::SetValue { .. } => "set_value",
+            RegistryOperation::DeleteValue { .. } => "delete_value",
+            RegistryOperation::CreateKey { .. } => "create_key",
+            RegistryOperation::DeleteKey { .. } => "delete_key",
+            RegistryOperation::Export { .. } => "export",
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            RegistryOperation::Query { path }
+            | RegistryOperation::SetValue { path, .. }
+            | RegistryOperation::DeleteValue { path, .. }
+            | RegistryOperation::CreateKey { path }
+            | RegistryOperation::DeleteKey { path }
+            | RegistryOperation::Export { path } => path,
+        }
+    }
+
+    pub fn value_name(&self) -> Option<&str> {
+        match self {
+            RegistryOperation::SetValue { name, .. } | RegistryOperation::DeleteValue { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Audit record of a registry operation, with affected data in `.reg` syntax
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryAuditEntry {
+    pub id: Uuid,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub session_id: String,
+    pub operation: String,
+    pub path: String,
+    pub value_name: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Policy-checked, audited registry access
+pub struct RegistryService {
+    backend: Box<dyn RegistryBackend>,
+    policy: RegistryPolicy,
+    audit_log: Mutex<Vec<RegistryAuditEntry>>,
+}
+
+impl RegistryService {
+    pub fn new(backend: Box<dyn RegistryBackend>, policy: RegistryPolicy) -> Self {
+        Self {
+            backend,
+            policy,
+            audit_log: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Run an operation on behalf of a session and record it in the audit log
+    pub fn execute(
+        &self,
+        session_id: &str,
+        operation: &RegistryOperation,
+    ) -> (Result<serde_json::Value>, RegistryAuditEntry) {
+        let mut entry = RegistryAuditEntry {
+            id: Uuid::new_v4(),
+            timestamp: chrono::Utc::now(),
+            session_id: session_id.to_string(),
+            operation: operation.name().to_string(),
+            path: operation.path().to_string(),
+            value_name: operation.value_name().map(str::to_string),
+            before: None,
+            after: None,
+            success: false,
+            error: None,
+        };
+
+        let result = self.apply(operation, &mut entry);
+        match &result {
+            Ok(_) => {
+                entry.success = true;
+                info!("Registry {} on {} for session {}", entry.operation, entry.path, session_id);
+            }
+            Err(e) => {
+                entry.error = Some(e.to_string());
+                warn!("Registry {} on {} failed: {}", entry.operation, entry.path, e);
+            }
+        }
+
+        let mut audit_log = self.audit_log.lock().unwrap();
+        audit_log.push(entry.clone());
+        if audit_log.len() > MAX_AUDIT_ENTRIES {
+            audit_log.remove(0);
+        }
+
+        (result, entry)
+    }
+
+    /// Snapshot of the audit log, oldest first
+    pub fn audit_log(&self) -> Vec<RegistryAuditEntry> {
+        self.audit_log.lock().unwrap().clone()
+    }
+
+    fn apply(
+        &self,
+        operation: &RegistryOperation,
+        entry: &mut RegistryAuditEntry,
+    ) -> Result<serde_json::Value> {
+        let path = RegistryPath::parse(operation.path())?;
+        entry.path = path.to_string();
+        self.policy.check_path(&path)?;
+
+        match operation {
+            RegistryOperation::Query { .. } => {
+                let info = RegistryKeyInfo {
+                    path: path.to_string(),
+                    subkeys: self.backend.list_subkeys(&path)?,
+                    values: self.backend.list_values(&path)?,
+                };
+                Ok(serde_json::to_value(info)?)
+            }
+            RegistryOperation::Export { .. } => {
+                Ok(serde_json::Value::String(self.export(&path)?))
+            }
+            RegistryOperation::SetValue { name, value, .. } => {
+                if !self.backend.key_exists(&path)? {
+                    return Err(anyhow::anyhow!("Registry key not found: {}", path));
+                }
+
+                entry.before = self.backend.get_value(&path, name)?
+                    .map(|data| format_reg_value(name, &data));
+                self.backend.set_value(&path, name, value)?;
+                entry.after = Some(format_reg_value(name, value));
+                Ok(serde_json::Value::Null)
+            }
+            RegistryOperation::DeleteValue { name, .. } => {
+                let data = self.backend.get_value(&path, name)?
+                    .ok_or_else(|| anyhow::anyhow!("Registry value not found: {}\\{}", path, name))?;
+
+                entry.before = Some(format_reg_value(name, &data));
+                self.backend.delete_value(&path, name)?;
+                entry.after = Some(format!("{}=-", format_value_name(name)));
+                Ok(serde_json::Value::Null)
+            }
+            RegistryOperation::CreateKey { .. } => {
+                if self.backend.key_exists(&path)? {
+                    return Err(anyhow::anyhow!("Registry key already exists: {}", path));
+                }
+
+                self.backend.create_key(&path)?;
+                entry.after = Some(format!("[{}]", path));
+                Ok(serde_json::Value::Null)
+            }
+            RegistryOperation::DeleteKey { .. } => {
+                if path.subkey.is_empty() {
+                    return Err(anyhow::anyhow!("Refusing to delete registry hive {}", path));
+                }
+
+                entry.before = Some(self.export(&path)?);
+                self.backend.delete_key(&path)?;
+                entry.after = Some(format!("[-{}]", path));
+                Ok(serde_json::Value::Null)
+            }
+        }
+    }
+
+    /// Export a key and its subkeys as `.reg` text
+    pub fn export(&self, path: &RegistryPath) -> Result<String> {
+        if !self.backend.key_exists(path)? {
+            return Err(anyhow::anyhow!("Registry key not found: {}", path));
+        }
+
+        let mut out = format!("{}\r\n\r\n", REG_FILE_HEADER);
+        self.export_key(path, &mut out)?;
+        Ok(out)
+    }
+
+    fn export_key(&self, path: &RegistryPath, out: &mut String) -> Result<()> {
+        out.push_str(&format!("[{}]\r\n", path));
+        for value in self.backend.list_values(path)? {
+            out.push_str(&format_reg_value(&value.name, &value.data));
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\n");
+
+        for subkey in self.backend.list_subkeys(path)? {
+            self.export_key(&path.child(&subkey), out)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape_reg_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn format_value_name(name: &str) -> String {
+    if name.is_empty() {
+        "@".to_string()
+    } else {
+        format!("\"{}\"", escape_reg_string(name))
+    }
+}
+
+fn hex_list(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Render a value as a `.reg` file line
+pub fn format_reg_value(name: &str, data: &RegistryData) -> String {
+    let rendered = match data {
+        RegistryData::String(s) => format!("\"{}\"", escape_reg_string(s)),
+        RegistryData::Dword(v) => format!("dword:{:08x}", v),
+        RegistryData::Binary(bytes) => format!("hex:{}", hex_list(bytes)),
+        other => format!("hex({:x}):{}", other.raw_type(), hex_list(&other.to_raw_bytes())),
+    };
+    format!("{}={}", format_value_name(name), rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// In-memory registry keyed by the display form of each path
+    #[derive(Default)]
+    struct MockRegistry {
+        keys: Mutex<BTreeMap<String, Vec<RegistryValue>>>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl MockRegistry {
+        fn with_key(self, path: &str, values: Vec<(&str, RegistryData)>) -> Self {
+            let values = values.into_iter()
+                .map(|(name, data)| RegistryValue { name: name.to_string(), data })
+                .collect();
+            self.keys.lock().unwrap().insert(RegistryPath::parse(path).unwrap().to_string(), values);
+            self
+        }
+    }
+
+    impl RegistryBackend for MockRegistry {
+        fn key_exists(&self, path: &RegistryPath) -> Result<bool> {
+            Ok(self.keys.lock().unwrap().contains_key(&path.to_string()))
+        }
+
+        fn list_subkeys(&self, path: &RegistryPath) -> Result<Vec<String>> {
+            let prefix = format!("{}\\", path);
+            Ok(self.keys.lock().unwrap().keys()
+                .filter_map(|key| key.strip_prefix(&prefix))
+                .filter(|rest| !rest.contains('\\'))
+                .map(str::to_string)
+                .collect())
+        }
+
+        fn list_values(&self, path: &RegistryPath) -> Result<Vec<RegistryValue>> {
+            self.keys.lock().unwrap().get(&path.to_string())
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Registry key not found: {}", path))
+        }
+
+        fn set_value(&self, path: &RegistryPath, name: &str, data: &RegistryData) -> Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            let mut keys = self.keys.lock().unwrap();
+            let values = keys.get_mut(&path.to_string()).unwrap();
+            values.retain(|v| v.name != name);
+            values.push(RegistryValue { name: name.to_string(), data: data.clone() });
+            Ok(())
+        }
+
+        fn delete_value(&self, path: &RegistryPath, name: &str) -> Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            let mut keys = self.keys.lock().unwrap();
+            keys.get_mut(&path.to_string()).unwrap().retain(|v| v.name != name);
+            Ok(())
+        }
+
+        fn create_key(&self, path: &RegistryPath) -> Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.keys.lock().unwrap().insert(path.to_string(), Vec::new());
+            Ok(())
+        }
+
+        fn delete_key(&self, path: &RegistryPath) -> Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            let own = path.to_string();
+            let prefix = format!("{}\\", own);
+            self.keys.lock().unwrap().retain(|key, _| key != &own && !key.starts_with(&prefix));
+            Ok(())
+        }
+    }
+
+    fn service(backend: MockRegistry) -> (RegistryService, Arc<AtomicUsize>) {
+        let writes = Arc::clone(&backend.writes);
+        let policy = RegistryPolicy {
+            enabled: true,
+            allowed_paths: vec!["HKCU\\Software\\GhostLink".to_string()],
+        };
+        (RegistryService::new(Box::new(backend), policy), writes)
+    }
+
+    #[test]
+    fn test_path_parsing_normalizes_hive_and_separators() {
+        let path = RegistryPath::parse(" hklm/SOFTWARE//Vendor\\App ").unwrap();
+        assert_eq!(path.hive, RegistryHive::LocalMachine);
+        assert_eq!(path.to_string(), "HKEY_LOCAL_MACHINE\\SOFTWARE\\Vendor\\App");
+        assert!(path.hive.requires_admin());
+        assert!(!RegistryPath::parse("HKCU").unwrap().hive.requires_admin());
+        assert!(RegistryPath::parse("HKXX\\Software").is_err());
+    }
+
+    #[test]
+    fn test_allowlist_blocks_paths_outside_policy() {
+        let backend = MockRegistry::default()
+            .with_key("HKLM\\SOFTWARE\\Vendor", vec![])
+            .with_key("HKCU\\Software\\GhostLinkEvil", vec![]);
+        let (service, writes) = service(backend);
+
+        for path in ["HKLM\\SOFTWARE\\Vendor", "HKCU\\Software\\GhostLinkEvil", "HKCU\\Software"] {
+            let (result, entry) = service.execute("s1", &RegistryOperation::SetValue {
+                path: path.to_string(),
+                name: "Level".to_string(),
+                value: RegistryData::Dword(1),
+            });
+            assert!(result.is_err(), "{} should be denied", path);
+            assert!(!entry.success);
+            assert!(entry.error.unwrap().contains("not allowed by policy"));
+            assert!(entry.before.is_none() && entry.after.is_none());
+        }
+
+        assert_eq!(writes.load(Ordering::SeqCst), 0);
+        assert_eq!(service.audit_log().len(), 3);
+    }
+
+    #[test]
+    fn test_set_value_audits_before_and_after() {
+        let backend = MockRegistry::default()
+            .with_key("HKCU\\Software\\GhostLink", vec![("Level", RegistryData::Dword(1))]);
+        let (service, writes) = service(backend);
+
+        let (result, entry) = service.execute("s1", &RegistryOperation::SetValue {
+            path: "hkcu/Software/GhostLink".to_string(),
+            name: "Level".to_string(),
+            value: RegistryData::Dword(10),
+        });
+
+        assert!(result.is_ok());
+        assert!(entry.success);
+        assert_eq!(entry.session_id, "s1");
+        assert_eq!(entry.operation, "set_value");
+        assert_eq!(entry.value_name.as_deref(), Some("Level"));
+        assert_eq!(entry.before.as_deref(), Some("\"Level\"=dword:00000001"));
+        assert_eq!(entry.after.as_deref(), Some("\"Level\"=dword:0000000a"));
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_delete_key_audits_exported_subtree() {
+        let backend = MockRegistry::default()
+            .with_key("HKCU\\Software\\GhostLink", vec![])
+            .with_key("HKCU\\Software\\GhostLink\\Cache", vec![("Path", RegistryData::String("C:\\Temp".to_string()))]);
+        let (service, _) = service(backend);
+
+        let (result, entry) = service.execute("s1", &RegistryOperation::DeleteKey {
+            path: "HKCU\\Software\\GhostLink\\Cache".to_string(),
+        });
+
+        assert!(result.is_ok());
+        let before = entry.before.unwrap();
+        assert!(before.contains("[HKEY_CURRENT_USER\\Software\\GhostLink\\Cache]"));
+        assert!(before.contains("\"Path\"=\"C:\\\\Temp\""));
+        assert_eq!(entry.after.as_deref(), Some("[-HKEY_CURRENT_USER\\Software\\GhostLink\\Cache]"));
+        assert!(!service.backend.key_exists(&RegistryPath::parse("HKCU\\Software\\GhostLink\\Cache").unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_export_reg_format() {
+        let backend = MockRegistry::default()
+            .with_key("HKCU\\Software\\GhostLink", vec![
+                ("", RegistryData::String("default".to_string())),
+                ("Home", RegistryData::ExpandString("%A%".to_string())),
+                ("Hosts", RegistryData::MultiString(vec!["a".to_string()])),
+                ("Blob", RegistryData::Binary(vec![0xde, 0xad])),
+            ])
+            .with_key("HKCU\\Software\\GhostLink\\Sub", vec![("Size", RegistryData::Qword(1))]);
+        let (service, _) = service(backend);
+
+        let (result, _) = service.execute("s1", &RegistryOperation::Export {
+            path: "HKCU\\Software\\GhostLink".to_string(),
+        });
+        let text = result.unwrap();
+        let text = text.as_str().unwrap();
+
+        let expected = "Windows Registry Editor Version 5.00\r\n\r\n\
+            [HKEY_CURRENT_USER\\Software\\GhostLink]\r\n\
+            @=\"default\"\r\n\
+            \"Home\"=hex(2):25,00,41,00,25,00,00,00\r\n\
+            \"Hosts\"=hex(7):61,00,00,00,00,00\r\n\
+            \"Blob\"=hex:de,ad\r\n\
+            \r\n\
+            [HKEY_CURRENT_USER\\Software\\GhostLink\\Sub]\r\n\
+            \"Size\"=hex(b):01,00,00,00,00,00,00,00\r\n\
+            \r\n";
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let values = [
+            RegistryData::String("text".to_string()),
+            RegistryData::MultiString(vec!["one".to_string(), "two".to_string()]),
+            RegistryData::Dword(0xdeadbeef),
+            RegistryData::Qword(u64::MAX),
+            RegistryData::Raw { kind: 0, bytes: vec![1, 2] },
+        ];
+        for value in values {
+            assert_eq!(RegistryData::from_raw(value.raw_type(), &value.to_raw_bytes()), value);
+        }
+    }
+}
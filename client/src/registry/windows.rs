@@ -0,0 +1,186 @@
+//! Win32 registry backend
+
+use anyhow::Result;
+use ::windows::core::{HRESULT, PCWSTR, PWSTR};
+use ::windows::Win32::Foundation::{ERROR_FILE_NOT_FOUND, ERROR_MORE_DATA, ERROR_NO_MORE_ITEMS};
+use ::windows::Win32::System::Registry::{
+    RegCloseKey, RegCreateKeyExW, RegDeleteTreeW, RegDeleteValueW, RegEnumKeyExW, RegEnumValueW,
+    RegOpenKeyExW, RegSetValueExW, HKEY, HKEY_CLASSES_ROOT, HKEY_CURRENT_CONFIG, HKEY_CURRENT_USER,
+    HKEY_LOCAL_MACHINE, HKEY_USERS, KEY_READ, KEY_SET_VALUE, KEY_WRITE, REG_OPTION_NON_VOLATILE,
+    REG_SAM_FLAGS, REG_VALUE_TYPE,
+};
+
+use super::{RegistryBackend, RegistryData, RegistryHive, RegistryPath, RegistryValue};
+
+/// Longest key name the registry allows, in UTF-16 units
+const MAX_KEY_NAME: usize = 256;
+
+/// Longest value name the registry allows, in UTF-16 units
+const MAX_VALUE_NAME: usize = 16384;
+
+/// Registry of the local machine
+pub struct WindowsRegistry;
+
+/// Open key handle closed on drop
+struct OwnedKey(HKEY);
+
+impl Drop for OwnedKey {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = RegCloseKey(self.0);
+        }
+    }
+}
+
+fn hive_handle(hive: RegistryHive) -> HKEY {
+    match hive {
+        RegistryHive::ClassesRoot => HKEY_CLASSES_ROOT,
+        RegistryHive::CurrentUser => HKEY_CURRENT_USER,
+        RegistryHive::LocalMachine => HKEY_LOCAL_MACHINE,
+        RegistryHive::Users => HKEY_USERS,
+        RegistryHive::CurrentConfig => HKEY_CURRENT_CONFIG,
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn is_error(error: &::windows::core::Error, code: ::windows::Win32::Foundation::WIN32_ERROR) -> bool {
+    error.code() == HRESULT::from(code)
+}
+
+impl WindowsRegistry {
+    fn open(&self, path: &RegistryPath, access: REG_SAM_FLAGS) -> Result<Option<OwnedKey>> {
+        let subkey = wide(&path.subkey);
+        let mut key = HKEY::default();
+
+        match unsafe { RegOpenKeyExW(hive_handle(path.hive), PCWSTR(subkey.as_ptr()), 0, access, &mut key) } {
+            Ok(()) => Ok(Some(OwnedKey(key))),
+            Err(e) if is_error(&e, ERROR_FILE_NOT_FOUND) => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("Failed to open {}: {}", path, e)),
+        }
+    }
+
+    fn open_existing(&self, path: &RegistryPath, access: REG_SAM_FLAGS) -> Result<OwnedKey> {
+        self.open(path, access)?
+            .ok_or_else(|| anyhow::anyhow!("Registry key not found: {}", path))
+    }
+}
+
+impl RegistryBackend for WindowsRegistry {
+    fn key_exists(&self, path: &RegistryPath) -> Result<bool> {
+        Ok(self.open(path, KEY_READ)?.is_some())
+    }
+
+    fn list_subkeys(&self, path: &RegistryPath) -> Result<Vec<String>> {
+        let key = self.open_existing(path, KEY_READ)?;
+        let mut names = Vec::new();
+        let mut buf = vec![0u16; MAX_KEY_NAME];
+
+        for index in 0.. {
+            let mut len = buf.len() as u32;
+            let result = unsafe {
+                RegEnumKeyExW(key.0, index, PWSTR(buf.as_mut_ptr()), &mut len, None, PWSTR::null(), None, None)
+            };
+            match result {
+                Ok(()) => names.push(String::from_utf16_lossy(&buf[..len as usize])),
+                Err(e) if is_error(&e, ERROR_NO_MORE_ITEMS) => break,
+                Err(e) => return Err(anyhow::anyhow!("Failed to enumerate {}: {}", path, e)),
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn list_values(&self, path: &RegistryPath) -> Result<Vec<RegistryValue>> {
+        let key = self.open_existing(path, KEY_READ)?;
+        let mut values = Vec::new();
+        let mut name_buf = vec![0u16; MAX_VALUE_NAME];
+        let mut data = vec![0u8; 1024];
+
+        let mut index = 0;
+        loop {
+            let mut name_len = name_buf.len() as u32;
+            let mut kind = 0u32;
+            let mut data_len = data.len() as u32;
+            let result = unsafe {
+                RegEnumValueW(
+                    key.0,
+                    index,
+                    PWSTR(name_buf.as_mut_ptr()),
+                    &mut name_len,
+                    None,
+                    Some(&mut kind),
+                    Some(data.as_mut_ptr()),
+                    Some(&mut data_len),
+                )
+            };
+
+            match result {
+                Ok(()) => {
+                    values.push(RegistryValue {
+                        name: String::from_utf16_lossy(&name_buf[..name_len as usize]),
+                        data: RegistryData::from_raw(kind, &data[..data_len as usize]),
+                    });
+                    index += 1;
+                }
+                // Grow the data buffer to the size reported and retry the same index
+                Err(e) if is_error(&e, ERROR_MORE_DATA) => data.resize(data_len as usize, 0),
+                Err(e) if is_error(&e, ERROR_NO_MORE_ITEMS) => break,
+                Err(e) => return Err(anyhow::anyhow!("Failed to read values of {}: {}", path, e)),
+            }
+        }
+
+        Ok(values)
+    }
+
+    fn set_value(&self, path: &RegistryPath, name: &str, data: &RegistryData) -> Result<()> {
+        let key = self.open_existing(path, KEY_SET_VALUE)?;
+        let name_w = wide(name);
+        let bytes = data.to_raw_bytes();
+
+        unsafe {
+            RegSetValueExW(key.0, PCWSTR(name_w.as_ptr()), 0, REG_VALUE_TYPE(data.raw_type()), Some(&bytes))
+        }
+        .map_err(|e| anyhow::anyhow!("Failed to set {}\\{}: {}", path, name, e))
+    }
+
+    fn delete_value(&self, path: &RegistryPath, name: &str) -> Result<()> {
+        let key = self.open_existing(path, KEY_SET_VALUE)?;
+        let name_w = wide(name);
+
+        unsafe { RegDeleteValueW(key.0, PCWSTR(name_w.as_ptr())) }
+            .map_err(|e| anyhow::anyhow!("Failed to delete {}\\{}: {}", path, name, e))
+    }
+
+    fn create_key(&self, path: &RegistryPath) -> Result<()> {
+        let subkey = wide(&path.subkey);
+        let mut key = HKEY::default();
+
+        unsafe {
+            RegCreateKeyExW(
+                hive_handle(path.hive),
+                PCWSTR(subkey.as_ptr()),
+                0,
+                PCWSTR::null(),
+                REG_OPTION_NON_VOLATILE,
+                KEY_WRITE,
+                None,
+                &mut key,
+                None,
+            )
+        }
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path, e))?;
+
+        drop(OwnedKey(key));
+        Ok(())
+    }
+
+    fn delete_key(&self, path: &RegistryPath) -> Result<()> {
+        let subkey = wide(&path.subkey);
+
+        unsafe { RegDeleteTreeW(hive_handle(path.hive), PCWSTR(subkey.as_ptr())) }
+            .map_err(|e| anyhow::anyhow!("Failed to delete {}: {}", path, e))
+    }
+}
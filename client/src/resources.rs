@@ -0,0 +1,282 @@
+//! Limits on what the agent takes from the machine it supports
+//!
+//! Three limits, each set in the config's `[resources]` section or by the
+//! server's policy, which wins:
+//!
+//! - a CPU budget for capture and encoding, in percent of one core, which
+//!   the capture loops pace themselves to (see [`crate::capture::budget`]);
+//! - a memory ceiling for frame and recording buffers. Buffers are charged
+//!   to a [`MemoryPool`] while they are held; past [`SHED_RATIO`] of the
+//!   ceiling the pool is under pressure, and the capture loop drops queued
+//!   frames while the recorder flushes early. A buffer that would cross the
+//!   ceiling is not taken at all.
+//! - background priority: nice 10 and best-effort I/O priority 7 on Linux,
+//!   nice 10 on other Unixes, `BELOW_NORMAL_PRIORITY_CLASS` on Windows.
+//!
+//! What capture takes against these limits goes out with every heartbeat.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use tracing::{info, warn};
+
+use crate::capture::budget;
+
+/// Share of the memory ceiling above which the pool sheds
+pub const SHED_RATIO: f64 = 0.8;
+
+/// Memory ceilings the agent accepts, in MB
+pub const MEMORY_LIMIT_RANGE: std::ops::RangeInclusive<u64> = 64..=16384;
+
+/// Nice value of background priority
+#[cfg(unix)]
+const BACKGROUND_NICE: libc::c_int = 10;
+
+/// Resource limits from the config's `[resources]` section
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourcePolicy {
+    /// Share of one core capture and encoding may use, in percent; unset
+    /// leaves them unlimited
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "crate::config::validate::cpu_budget")]
+    pub cpu_budget_percent: Option<u32>,
+    /// Memory frame and recording buffers may hold, in MB
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "crate::config::validate::memory_limit")]
+    pub memory_limit_mb: Option<u64>,
+    /// Run below normal CPU and I/O priority
+    pub background_priority: bool,
+}
+
+/// Capture and recording load against the limits, as the heartbeat reports it
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceUsage {
+    /// Capture and encode time, in percent of one core
+    pub cpu_percent: f32,
+    pub cpu_budget_percent: Option<u32>,
+    /// Bytes held by frame and recording buffers
+    pub memory_used: u64,
+    pub memory_limit: Option<u64>,
+    /// Frames delayed to stay within the CPU budget
+    pub frames_throttled: u64,
+    /// Queued frames dropped to stay under the memory limit
+    pub frames_shed: u64,
+}
+
+/// Accounts for the bytes buffers hold against a ceiling
+#[derive(Debug, Default)]
+pub struct MemoryPool {
+    /// Ceiling in bytes; 0 for none
+    limit: AtomicU64,
+    used: AtomicU64,
+    shed: AtomicU64,
+}
+
+/// Bytes charged to a pool, given back when dropped
+#[derive(Debug)]
+pub struct Lease<'a> {
+    pool: &'a MemoryPool,
+    bytes: u64,
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.pool.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+impl MemoryPool {
+    pub const fn new() -> Self {
+        Self { limit: AtomicU64::new(0), used: AtomicU64::new(0), shed: AtomicU64::new(0) }
+    }
+
+    pub fn set_limit(&self, bytes: Option<u64>) {
+        self.limit.store(bytes.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|&limit| limit > 0)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// Charge `bytes` to the pool, unless that would cross the ceiling
+    pub fn reserve(&self, bytes: usize) -> Option<Lease<'_>> {
+        let bytes = bytes as u64;
+        let limit = self.limit();
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let total = used.saturating_add(bytes);
+                limit.is_none_or(|limit| total <= limit).then_some(total)
+            })
+            .ok()?;
+        Some(Lease { pool: self, bytes })
+    }
+
+    /// Whether buffers should be given back before more are taken
+    pub fn under_pressure(&self) -> bool {
+        self.limit().is_some_and(|limit| self.used() as f64 >= limit as f64 * SHED_RATIO)
+    }
+
+    /// Count frames dropped to relieve the pool
+    pub fn note_shed(&self, frames: u64) {
+        self.shed.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    /// Frames dropped to relieve the pool so far
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+static MEMORY_POOL: MemoryPool = MemoryPool::new();
+
+/// The pool every frame and recording buffer of the agent is charged to
+pub fn memory_pool() -> &'static MemoryPool {
+    &MEMORY_POOL
+}
+
+/// Whether background priority is in force
+static BACKGROUND: AtomicBool = AtomicBool::new(false);
+
+/// Put `policy` in force for capture, recording and the agent's priority
+pub fn apply(policy: &ResourcePolicy) {
+    budget::shared().lock().set_percent(policy.cpu_budget_percent);
+    memory_pool().set_limit(policy.memory_limit_mb.map(|mb| mb * 1024 * 1024));
+
+    if BACKGROUND.swap(policy.background_priority, Ordering::AcqRel) != policy.background_priority {
+        match set_background_priority(policy.background_priority) {
+            Ok(()) if policy.background_priority => info!("Running at background priority"),
+            Ok(()) => info!("Running at normal priority"),
+            Err(e) => warn!("Failed to change the agent's priority: {}", e),
+        }
+    }
+}
+
+/// Load against the limits, for the heartbeat; none while no limit is set
+/// and capture has not run
+pub fn usage() -> Option<ResourceUsage> {
+    let budget = budget::shared().lock();
+    let pool = memory_pool();
+    let usage = ResourceUsage {
+        cpu_percent: budget.utilization(Instant::now()) as f32,
+        cpu_budget_percent: budget.percent(),
+        memory_used: pool.used(),
+        memory_limit: pool.limit(),
+        frames_throttled: budget.throttled(),
+        frames_shed: pool.shed(),
+    };
+    (usage != ResourceUsage::default()).then_some(usage)
+}
+
+/// Lower the whole process below normal priority, or restore it
+#[cfg(target_os = "linux")]
+fn set_background_priority(background: bool) -> std::io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let (nice, ioprio) = if background {
+        (BACKGROUND_NICE, (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 7)
+    } else {
+        (0, (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 4)
+    };
+
+    // Both are per thread on Linux, so every running thread is changed;
+    // threads started later inherit from the one starting them
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        let Some(tid) = entry?.file_name().to_str().and_then(|name| name.parse::<libc::id_t>().ok()) else {
+            continue;
+        };
+        // SAFETY: plain syscalls on a thread id of this process
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, tid, nice) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, ioprio) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_background_priority(background: bool) -> std::io::Result<()> {
+    let nice = if background { BACKGROUND_NICE } else { 0 };
+    // SAFETY: plain syscall on this process
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_background_priority(background: bool) -> std::io::Result<()> {
+    use windows::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, BELOW_NORMAL_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS,
+    };
+
+    let class = if background { BELOW_NORMAL_PRIORITY_CLASS } else { NORMAL_PRIORITY_CLASS };
+    // SAFETY: the pseudo handle of the current process needs no closing
+    unsafe { SetPriorityClass(GetCurrentProcess(), class) }.map_err(std::io::Error::other)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn set_background_priority(_background: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "process priority is not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_sheds_before_its_ceiling() {
+        let pool = MemoryPool::new();
+        let frame = 1024 * 1024;
+        // Unlimited until a ceiling is set
+        let unlimited: Vec<_> = (0..20).map(|_| pool.reserve(frame).unwrap()).collect();
+        assert!(!pool.under_pressure());
+        drop(unlimited);
+        assert_eq!(pool.used(), 0);
+
+        pool.set_limit(Some(10 * frame as u64));
+        let mut queued: Vec<_> = (0..7).map(|_| pool.reserve(frame).unwrap()).collect();
+        assert!(!pool.under_pressure());
+        queued.push(pool.reserve(frame).unwrap());
+        assert!(pool.under_pressure());
+
+        // The ceiling itself is never crossed
+        queued.push(pool.reserve(2 * frame).unwrap());
+        assert!(pool.reserve(1).is_none());
+        assert_eq!(pool.used(), 10 * frame as u64);
+
+        // Dropping the oldest queued frames relieves the pressure
+        while pool.under_pressure() {
+            queued.remove(0);
+            pool.note_shed(1);
+        }
+        assert_eq!(pool.shed(), 3);
+        assert_eq!(pool.used(), 7 * frame as u64);
+        assert!(pool.reserve(frame).is_some());
+
+        drop(queued);
+        assert_eq!(pool.used(), 0);
+        pool.set_limit(None);
+        assert_eq!(pool.limit(), None);
+    }
+
+    #[test]
+    fn test_policy_parses_and_checks_ranges() {
+        let policy: ResourcePolicy = toml::from_str("cpu_budget_percent = 25\nmemory_limit_mb = 256\nbackground_priority = true").unwrap();
+        assert_eq!(policy.cpu_budget_percent, Some(25));
+        assert_eq!(policy.memory_limit_mb, Some(256));
+        assert!(policy.background_priority);
+        assert_eq!(toml::from_str::<ResourcePolicy>("").unwrap(), ResourcePolicy::default());
+
+        assert!(toml::from_str::<ResourcePolicy>("cpu_budget_percent = 150").is_err());
+        assert!(toml::from_str::<ResourcePolicy>("memory_limit_mb = 1").is_err());
+    }
+}
@@ -0,0 +1,399 @@
+//! Session helper supervision on Windows
+//!
+//! The service keeps one helper running in whichever session owns the
+//! console. When a user is logged on, the helper runs with their token
+//! (`WTSQueryUserToken`); at the logon screen it runs as LocalSystem, moved
+//! into the console session, on the Winlogon desktop. Each helper gets a
+//! fresh pipe name, and the service only talks to the process it started.
+
+#![allow(dead_code)]
+
+use std::os::windows::io::AsRawHandle;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeServer, ServerOptions};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+use windows::core::{w, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{CloseHandle, LocalFree, BOOL, HANDLE, HLOCAL, WAIT_OBJECT_0};
+use windows::Win32::Security::Authorization::{
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows::Win32::Security::{
+    DuplicateTokenEx, SecurityImpersonation, SetTokenInformation, TokenPrimary, TokenSessionId,
+    PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES, TOKEN_ALL_ACCESS, TOKEN_DUPLICATE,
+};
+use windows::Win32::System::Pipes::GetNamedPipeClientProcessId;
+use windows::Win32::System::RemoteDesktop::{ProcessIdToSessionId, WTSGetActiveConsoleSessionId, WTSQueryUserToken};
+use windows::Win32::System::Threading::{
+    CreateProcessAsUserW, GetCurrentProcess, OpenProcessToken, TerminateProcess, WaitForSingleObject,
+    CREATE_NO_WINDOW, PROCESS_INFORMATION, STARTUPINFOW,
+};
+
+use super::helper::{self, read_message, should_restart, write_message, Control, HelperMessage, SessionChange};
+use crate::capture::Frame;
+
+/// How long a new helper has to connect and introduce itself
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a helper gets to exit after being asked to
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+/// A helper that dies sooner than this is restarted with a delay
+const MIN_HEALTHY_RUN: Duration = Duration::from_secs(10);
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// SYSTEM gets full control; interactive users may read and write, which
+/// covers a helper running with the console user's token
+const PIPE_SDDL: PCWSTR = w!("D:P(A;;GA;;;SY)(A;;GRGW;;;IU)");
+
+/// Handle the agent uses to reach the helper in the console session
+#[derive(Clone)]
+pub struct SessionHelper {
+    commands: mpsc::Sender<Control>,
+    frames: broadcast::Sender<Arc<Frame>>,
+}
+
+impl SessionHelper {
+    /// Frames captured by the helper, once capture has been requested
+    pub fn subscribe_frames(&self) -> broadcast::Receiver<Arc<Frame>> {
+        self.frames.subscribe()
+    }
+
+    /// Start (or with 0, stop) capturing in the console session
+    pub async fn request_frames(&self, fps: u32) -> Result<()> {
+        self.send(Control::Capture { fps }).await
+    }
+
+    /// Inject an input event in the console session
+    pub async fn send_input(&self, event: serde_json::Value) -> Result<()> {
+        self.send(Control::Input { event }).await
+    }
+
+    async fn send(&self, control: Control) -> Result<()> {
+        self.commands
+            .send(control)
+            .await
+            .map_err(|_| anyhow!("session helper supervisor has stopped"))
+    }
+}
+
+/// Messages from the service control handler to the supervisor
+#[derive(Debug, Clone, Copy)]
+pub enum SupervisorEvent {
+    SessionChanged { change: SessionChange, session_id: u32 },
+    Shutdown,
+}
+
+/// Start supervising the helper; events come from the SCM control handler
+pub fn spawn_supervisor() -> (SessionHelper, mpsc::UnboundedSender<SupervisorEvent>) {
+    let (commands_tx, commands) = mpsc::channel(256);
+    let (frames, _) = broadcast::channel(4);
+    let (events_tx, events) = mpsc::unbounded_channel();
+
+    let supervisor = Supervisor {
+        commands,
+        events,
+        frames: frames.clone(),
+        fps: 0,
+    };
+    tokio::spawn(supervisor.run());
+
+    (SessionHelper { commands: commands_tx, frames }, events_tx)
+}
+
+struct Supervisor {
+    commands: mpsc::Receiver<Control>,
+    events: mpsc::UnboundedReceiver<SupervisorEvent>,
+    frames: broadcast::Sender<Arc<Frame>>,
+    /// Capture rate last asked for, replayed to every new helper
+    fps: u32,
+}
+
+enum Outcome {
+    Restart,
+    Exited,
+    Shutdown,
+}
+
+impl Supervisor {
+    async fn run(mut self) {
+        loop {
+            let Some(session) = console_session() else {
+                info!("No session is attached to the console; waiting for one");
+                if !self.wait_for_session_change(None).await {
+                    return;
+                }
+                continue;
+            };
+
+            let started = Instant::now();
+            let outcome = match self.run_in_session(session).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    error!("Session helper in session {} failed: {:#}", session, e);
+                    Outcome::Exited
+                }
+            };
+
+            match outcome {
+                Outcome::Shutdown => return,
+                Outcome::Restart => info!("Restarting the session helper for the new console session"),
+                Outcome::Exited if started.elapsed() < MIN_HEALTHY_RUN => {
+                    // Don't spin on a helper that can't start; a session
+                    // change still restarts it straight away
+                    tokio::select! {
+                        _ = sleep(RESTART_DELAY) => {}
+                        event = self.events.recv() => {
+                            if matches!(event, None | Some(SupervisorEvent::Shutdown)) {
+                                return;
+                            }
+                        }
+                    }
+                }
+                Outcome::Exited => warn!("Session helper exited; restarting it"),
+            }
+        }
+    }
+
+    /// Wait until a session change makes a helper worth (re)starting;
+    /// `false` on shutdown
+    async fn wait_for_session_change(&mut self, helper_session: Option<u32>) -> bool {
+        loop {
+            match self.events.recv().await {
+                None | Some(SupervisorEvent::Shutdown) => return false,
+                Some(SupervisorEvent::SessionChanged { change, session_id }) => {
+                    if should_restart(change, session_id, helper_session, console_session()) {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_in_session(&mut self, session: u32) -> Result<Outcome> {
+        let pipe_name = format!(r"\\.\pipe\ghostlink-helper-{}", Uuid::new_v4());
+        let pipe = create_pipe(&pipe_name)?;
+        let process = HelperProcess::spawn(session, &pipe_name)?;
+        info!("Started session helper (pid {}) in session {}", process.pid, session);
+
+        timeout(CONNECT_TIMEOUT, pipe.connect())
+            .await
+            .map_err(|_| anyhow!("session helper did not connect"))??;
+        let client_pid = pipe_client_pid(&pipe)?;
+        if client_pid != process.pid {
+            bail!("pipe was opened by pid {} instead of the session helper", client_pid);
+        }
+
+        let (mut reader, mut writer) = tokio::io::split(pipe);
+        match timeout(CONNECT_TIMEOUT, read_message(&mut reader)).await {
+            Ok(Ok(Some(HelperMessage::Control(Control::Hello { pid, session_id })))) => {
+                debug!("Session helper {} ready in session {}", pid, session_id);
+            }
+            Ok(Ok(other)) => bail!("session helper sent {:?} instead of its hello", other),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => bail!("session helper did not say hello"),
+        }
+        if self.fps > 0 {
+            write_message(&mut writer, &HelperMessage::Control(Control::Capture { fps: self.fps })).await?;
+        }
+
+        // Reads are not cancel-safe, so they get a task of their own
+        let (incoming_tx, mut incoming) = mpsc::channel(8);
+        let reader_task = tokio::spawn(async move {
+            loop {
+                match read_message(&mut reader).await {
+                    Ok(Some(message)) => {
+                        if incoming_tx.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Session helper pipe failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let outcome = loop {
+            tokio::select! {
+                message = incoming.recv() => match message {
+                    Some(HelperMessage::Frame(frame)) => {
+                        // Nobody listening is fine; frames are only wanted while streaming
+                        let _ = self.frames.send(Arc::new(frame));
+                    }
+                    Some(HelperMessage::Control(Control::Error { message })) => {
+                        warn!("Session helper: {}", message);
+                    }
+                    Some(HelperMessage::Control(other)) => debug!("Ignoring {:?} from the session helper", other),
+                    None => break Outcome::Exited,
+                },
+                command = self.commands.recv() => {
+                    let Some(command) = command else { break Outcome::Shutdown };
+                    if let Control::Capture { fps } = command {
+                        self.fps = fps;
+                    }
+                    if let Err(e) = write_message(&mut writer, &HelperMessage::Control(command)).await {
+                        warn!("Could not reach the session helper: {}", e);
+                        break Outcome::Exited;
+                    }
+                },
+                event = self.events.recv() => match event {
+                    None | Some(SupervisorEvent::Shutdown) => break Outcome::Shutdown,
+                    Some(SupervisorEvent::SessionChanged { change, session_id }) => {
+                        debug!("Session {} changed: {:?}", session_id, change);
+                        if should_restart(change, session_id, Some(session), console_session()) {
+                            break Outcome::Restart;
+                        }
+                    }
+                },
+            }
+        };
+
+        if !matches!(outcome, Outcome::Exited) {
+            let _ = write_message(&mut writer, &HelperMessage::Control(Control::Shutdown)).await;
+        }
+        reader_task.abort();
+        process.stop().await;
+        Ok(outcome)
+    }
+}
+
+/// Helper side: connect back to the service and serve this session
+pub async fn run_helper(pipe_name: &str) -> Result<()> {
+    let pipe = ClientOptions::new()
+        .open(pipe_name)
+        .with_context(|| format!("cannot open {}", pipe_name))?;
+    let mut session = 0u32;
+    unsafe { ProcessIdToSessionId(std::process::id(), &mut session) }.context("ProcessIdToSessionId failed")?;
+    helper::serve(pipe, session).await
+}
+
+/// The session attached to the physical console, if any
+fn console_session() -> Option<u32> {
+    let session = unsafe { WTSGetActiveConsoleSessionId() };
+    (session != u32::MAX).then_some(session)
+}
+
+fn create_pipe(name: &str) -> Result<NamedPipeServer> {
+    unsafe {
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(PIPE_SDDL, SDDL_REVISION_1, &mut descriptor, None)
+            .context("ConvertStringSecurityDescriptorToSecurityDescriptorW failed")?;
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.0,
+            bInheritHandle: BOOL::from(false),
+        };
+
+        let pipe = ServerOptions::new()
+            .first_pipe_instance(true)
+            .reject_remote_clients(true)
+            .max_instances(1)
+            .create_with_security_attributes_raw(name, &mut attributes as *mut SECURITY_ATTRIBUTES as *mut _);
+        let _ = LocalFree(HLOCAL(descriptor.0));
+        Ok(pipe?)
+    }
+}
+
+fn pipe_client_pid(pipe: &NamedPipeServer) -> Result<u32> {
+    let mut pid = 0u32;
+    unsafe { GetNamedPipeClientProcessId(HANDLE(pipe.as_raw_handle() as isize), &mut pid) }
+        .context("GetNamedPipeClientProcessId failed")?;
+    Ok(pid)
+}
+
+/// A helper process started in another session
+struct HelperProcess {
+    handle: HANDLE,
+    pid: u32,
+}
+
+unsafe impl Send for HelperProcess {}
+
+impl HelperProcess {
+    fn spawn(session: u32, pipe_name: &str) -> Result<Self> {
+        let exe = std::env::current_exe().context("cannot locate the agent executable")?;
+        let command_line = format!("\"{}\" session-helper --pipe {}", exe.display(), pipe_name);
+        let mut command_line: Vec<u16> = command_line.encode_utf16().chain(std::iter::once(0)).collect();
+
+        unsafe {
+            let (token, desktop) = session_token(session)?;
+            let startup = STARTUPINFOW {
+                cb: std::mem::size_of::<STARTUPINFOW>() as u32,
+                lpDesktop: PWSTR(desktop.as_ptr() as *mut u16),
+                ..Default::default()
+            };
+            let mut process = PROCESS_INFORMATION::default();
+            let result = CreateProcessAsUserW(
+                token,
+                PCWSTR::null(),
+                PWSTR(command_line.as_mut_ptr()),
+                None,
+                None,
+                BOOL::from(false),
+                CREATE_NO_WINDOW,
+                None,
+                PCWSTR::null(),
+                &startup,
+                &mut process,
+            );
+            let _ = CloseHandle(token);
+            result.context("CreateProcessAsUserW failed")?;
+
+            let _ = CloseHandle(process.hThread);
+            Ok(Self {
+                handle: process.hProcess,
+                pid: process.dwProcessId,
+            })
+        }
+    }
+
+    /// Give the helper a moment to exit on its own, then end it
+    async fn stop(self) {
+        let deadline = Instant::now() + EXIT_GRACE;
+        while Instant::now() < deadline {
+            if unsafe { WaitForSingleObject(self.handle, 0) } == WAIT_OBJECT_0 {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        warn!("Session helper {} did not exit; terminating it", self.pid);
+        let _ = unsafe { TerminateProcess(self.handle, 1) };
+    }
+}
+
+impl Drop for HelperProcess {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.handle) };
+    }
+}
+
+/// Token and desktop for a helper in `session`: the logged-on user's, or
+/// our own LocalSystem token moved into the session at the logon screen
+unsafe fn session_token(session: u32) -> Result<(HANDLE, PCWSTR)> {
+    let mut token = HANDLE::default();
+    if WTSQueryUserToken(session, &mut token).is_ok() {
+        return Ok((token, w!("winsta0\\default")));
+    }
+
+    let mut own = HANDLE::default();
+    OpenProcessToken(GetCurrentProcess(), TOKEN_DUPLICATE, &mut own).context("OpenProcessToken failed")?;
+    let duplicated = DuplicateTokenEx(own, TOKEN_ALL_ACCESS, None, SecurityImpersonation, TokenPrimary, &mut token);
+    let _ = CloseHandle(own);
+    duplicated.context("DuplicateTokenEx failed")?;
+
+    if let Err(e) = SetTokenInformation(
+        token,
+        TokenSessionId,
+        &session as *const u32 as *const _,
+        std::mem::size_of::<u32>() as u32,
+    ) {
+        let _ = CloseHandle(token);
+        return Err(e).context("SetTokenInformation failed");
+    }
+    Ok((token, w!("winsta0\\winlogon")))
+}
@@ -0,0 +1,346 @@
+//! Session helper protocol
+//!
+//! On Windows the agent service runs in session 0, which has no desktop of
+//! its own: capture and input injection have to happen in the session the
+//! user sits at. The service starts a helper process there and talks to it
+//! over a named pipe. Each message on the pipe is a little-endian `u32`
+//! length, a one-byte kind and the payload. Frames carry raw pixels behind a
+//! fixed binary header; everything else is JSON.
+
+#![allow(dead_code)]
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::{info, warn};
+
+use crate::capture::{Frame, PixelFormat, ScreenCapture};
+use crate::input::InputController;
+use crate::session::SessionType;
+
+/// Largest message accepted from the pipe; an 8K BGRA frame fits comfortably
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024 * 1024;
+
+const KIND_CONTROL: u8 = 1;
+const KIND_FRAME: u8 = 2;
+
+/// width, height, stride, pixel format, timestamp
+const FRAME_HEADER_LEN: usize = 4 + 4 + 4 + 1 + 8;
+
+/// Everything on the pipe except frames
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Control {
+    /// First message from the helper once it is connected
+    Hello { pid: u32, session_id: u32 },
+    /// Start capturing at this rate; 0 stops
+    Capture { fps: u32 },
+    /// Input event from the technician, in the relay's JSON shape
+    Input { event: serde_json::Value },
+    /// Helper-side failure the service should log
+    Error { message: String },
+    /// Ask the helper to exit
+    Shutdown,
+}
+
+#[derive(Debug, Clone)]
+pub enum HelperMessage {
+    Control(Control),
+    Frame(Frame),
+}
+
+fn pixel_format_code(format: PixelFormat) -> u8 {
+    match format {
+        PixelFormat::RGBA => 0,
+        PixelFormat::BGRA => 1,
+        PixelFormat::RGB => 2,
+        PixelFormat::BGR => 3,
+        PixelFormat::YUV420 => 4,
+        PixelFormat::NV12 => 5,
+    }
+}
+
+fn pixel_format_from_code(code: u8) -> Result<PixelFormat> {
+    Ok(match code {
+        0 => PixelFormat::RGBA,
+        1 => PixelFormat::BGRA,
+        2 => PixelFormat::RGB,
+        3 => PixelFormat::BGR,
+        4 => PixelFormat::YUV420,
+        5 => PixelFormat::NV12,
+        _ => bail!("unknown pixel format {}", code),
+    })
+}
+
+/// Write one message
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &HelperMessage) -> Result<()> {
+    let (kind, payload) = match message {
+        HelperMessage::Control(control) => (KIND_CONTROL, serde_json::to_vec(control)?),
+        HelperMessage::Frame(frame) => {
+            let mut payload = Vec::with_capacity(FRAME_HEADER_LEN + frame.data.len());
+            payload.extend_from_slice(&frame.width.to_le_bytes());
+            payload.extend_from_slice(&frame.height.to_le_bytes());
+            payload.extend_from_slice(&frame.stride.to_le_bytes());
+            payload.push(pixel_format_code(frame.pixel_format));
+            payload.extend_from_slice(&frame.timestamp.to_le_bytes());
+            payload.extend_from_slice(&frame.data);
+            (KIND_FRAME, payload)
+        }
+    };
+    if payload.len() + 1 > MAX_MESSAGE_LEN {
+        bail!("message of {} bytes is too large for the helper pipe", payload.len());
+    }
+
+    writer.write_all(&((payload.len() + 1) as u32).to_le_bytes()).await?;
+    writer.write_all(&[kind]).await?;
+    writer.write_all(&payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read one message; `None` once the other end has closed the pipe cleanly
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<HelperMessage>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        bail!("invalid helper message length {}", len);
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    let payload = &body[1..];
+
+    match body[0] {
+        KIND_CONTROL => Ok(Some(HelperMessage::Control(serde_json::from_slice(payload)?))),
+        KIND_FRAME => {
+            if payload.len() < FRAME_HEADER_LEN {
+                bail!("truncated frame header");
+            }
+            let u32_at = |at: usize| u32::from_le_bytes(payload[at..at + 4].try_into().unwrap());
+            Ok(Some(HelperMessage::Frame(Frame {
+                width: u32_at(0),
+                height: u32_at(4),
+                stride: u32_at(8),
+                pixel_format: pixel_format_from_code(payload[12])?,
+                timestamp: u64::from_le_bytes(payload[13..21].try_into().unwrap()),
+                data: payload[FRAME_HEADER_LEN..].to_vec(),
+            })))
+        }
+        kind => Err(anyhow!("unknown helper message kind {}", kind)),
+    }
+}
+
+/// WTS session notifications the service reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionChange {
+    ConsoleConnect,
+    ConsoleDisconnect,
+    RemoteConnect,
+    RemoteDisconnect,
+    Logon,
+    Logoff,
+    Lock,
+    Unlock,
+    Other,
+}
+
+/// Whether the helper has to be restarted after a session change
+///
+/// The helper belongs in whichever session owns the console. Fast user
+/// switching and RDP connections move a different session there; a logon or
+/// logoff in the helper's own session swaps the token it should run with.
+pub fn should_restart(
+    change: SessionChange,
+    changed_session: u32,
+    helper_session: Option<u32>,
+    console_session: Option<u32>,
+) -> bool {
+    if helper_session != console_session {
+        return true;
+    }
+    matches!(change, SessionChange::Logon | SessionChange::Logoff) && helper_session == Some(changed_session)
+}
+
+fn frame_period(fps: u32) -> Duration {
+    if fps == 0 {
+        Duration::from_secs(3600)
+    } else {
+        Duration::from_secs(1) / fps
+    }
+}
+
+/// Helper side: capture and inject in this session on behalf of the service
+/// at the other end of `stream`, until it asks us to stop or goes away
+pub async fn serve<S>(stream: S, session_id: u32) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    write_message(
+        &mut writer,
+        &HelperMessage::Control(Control::Hello { pid: std::process::id(), session_id }),
+    )
+    .await?;
+
+    let mut capturer = ScreenCapture::create_platform_capturer().await?;
+    capturer.initialize().await?;
+    let input = InputController::new(SessionType::Console).await?;
+
+    // Reads are not cancel-safe, so they get a task of their own
+    let (control_tx, mut controls) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            match read_message(&mut reader).await {
+                Ok(Some(HelperMessage::Control(control))) => {
+                    if control_tx.send(control).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(Some(HelperMessage::Frame(_))) => warn!("Ignoring frame sent to the session helper"),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Session helper pipe failed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut fps = 0;
+    let mut ticker = interval(frame_period(fps));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            control = controls.recv() => match control {
+                None | Some(Control::Shutdown) => break,
+                Some(Control::Capture { fps: rate }) => {
+                    fps = rate;
+                    ticker = interval(frame_period(fps));
+                    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                }
+                Some(Control::Input { event }) => {
+                    if let Err(e) = input.handle_event(&event).await {
+                        let message = Control::Error { message: format!("input failed: {}", e) };
+                        write_message(&mut writer, &HelperMessage::Control(message)).await?;
+                    }
+                }
+                Some(other) => warn!("Unexpected message for the session helper: {:?}", other),
+            },
+            _ = ticker.tick(), if fps > 0 => {
+                let message = match capturer.capture_frame().await {
+                    Ok(frame) => HelperMessage::Frame(frame),
+                    Err(e) => HelperMessage::Control(Control::Error { message: format!("capture failed: {}", e) }),
+                };
+                write_message(&mut writer, &message).await?;
+            }
+        }
+    }
+
+    info!("Session helper for session {} stopping", session_id);
+    capturer.cleanup().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> Frame {
+        Frame {
+            data: (0..64u8).collect(),
+            width: 4,
+            height: 4,
+            pixel_format: PixelFormat::BGRA,
+            stride: 16,
+            timestamp: 1_700_000_000_123,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let messages = vec![
+            HelperMessage::Control(Control::Hello { pid: 42, session_id: 1 }),
+            HelperMessage::Frame(frame()),
+            HelperMessage::Control(Control::Input {
+                event: serde_json::json!({"type": "MouseMove", "x": 10, "y": 20}),
+            }),
+            HelperMessage::Control(Control::Shutdown),
+        ];
+
+        let writer = tokio::spawn(async move {
+            for message in &messages {
+                write_message(&mut client, message).await.unwrap();
+            }
+        });
+
+        match read_message(&mut server).await.unwrap() {
+            Some(HelperMessage::Control(Control::Hello { pid, session_id })) => assert_eq!((pid, session_id), (42, 1)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match read_message(&mut server).await.unwrap() {
+            Some(HelperMessage::Frame(received)) => {
+                let sent = frame();
+                assert_eq!(received.data, sent.data);
+                assert_eq!((received.width, received.height, received.stride), (4, 4, 16));
+                assert_eq!(received.pixel_format, PixelFormat::BGRA);
+                assert_eq!(received.timestamp, sent.timestamp);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match read_message(&mut server).await.unwrap() {
+            Some(HelperMessage::Control(Control::Input { event })) => assert_eq!(event["x"], 10),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            read_message(&mut server).await.unwrap(),
+            Some(HelperMessage::Control(Control::Shutdown))
+        ));
+
+        writer.await.unwrap();
+        assert!(read_message(&mut server).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_messages_are_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&((MAX_MESSAGE_LEN + 1) as u32).to_le_bytes()).await.unwrap();
+        assert!(read_message(&mut server).await.is_err());
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&3u32.to_le_bytes()).await.unwrap();
+        client.write_all(&[KIND_FRAME, 0, 0]).await.unwrap();
+        assert!(read_message(&mut server).await.is_err());
+
+        // The pipe closing halfway through a message is an error, not a clean close
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&10u32.to_le_bytes()).await.unwrap();
+        client.write_all(&[KIND_CONTROL, b'{']).await.unwrap();
+        drop(client);
+        assert!(read_message(&mut server).await.is_err());
+    }
+
+    #[test]
+    fn test_restart_follows_the_console() {
+        // Fast user switching: another session now owns the console
+        assert!(should_restart(SessionChange::ConsoleConnect, 3, Some(1), Some(3)));
+        // RDP took the console user away; nobody is at the console
+        assert!(should_restart(SessionChange::ConsoleDisconnect, 1, Some(1), None));
+        // A user logged on at the logon screen the helper was showing
+        assert!(should_restart(SessionChange::Logon, 1, Some(1), Some(1)));
+        // Nothing moved
+        assert!(!should_restart(SessionChange::Lock, 1, Some(1), Some(1)));
+        assert!(!should_restart(SessionChange::RemoteConnect, 2, Some(1), Some(1)));
+        assert!(!should_restart(SessionChange::Logoff, 2, Some(1), Some(1)));
+        // Helper not running and still no console session
+        assert!(!should_restart(SessionChange::ConsoleDisconnect, 1, None, None));
+    }
+}
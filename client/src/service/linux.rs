@@ -1,106 +1,724 @@
-use anyhow::Result;
+//! Linux service management
+//!
+//! systemd is the normal case: a system unit when installed as root, a
+//! `--user` unit otherwise. Machines without systemd get an OpenRC or
+//! sysvinit script instead, which always needs root. Every command goes
+//! through [`CommandRunner`] so the install and removal steps can be tested.
+
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveDateTime, TimeZone};
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use tracing::{info, error};
+use tracing::{info, warn};
+
+use super::{InstallOptions, ServiceState, ServiceStatus};
+use crate::error::{GhostLinkError, ServiceError};
 
 const SERVICE_NAME: &str = "atlasconnect-agent";
 const SERVICE_DESCRIPTION: &str = "AtlasConnect Remote Access Agent";
 
-pub fn install_service(server_url: &str) -> Result<()> {
-    info!("Installing systemd service for AtlasConnect");
-    
-    let exe_path = std::env::current_exe()?;
-    let service_content = format!(
-        r#"[Unit]
-Description={}
-After=network.target
-StartLimitIntervalSec=0
-
-[Service]
-Type=simple
-Restart=always
-RestartSec=5
-User=root
-ExecStart={} start --server {}
-StandardOutput=journal
-StandardError=journal
-
-[Install]
-WantedBy=multi-user.target
-"#,
-        SERVICE_DESCRIPTION,
-        exe_path.display(),
-        server_url
+/// Output of a finished command
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs init system commands; mocked in tests
+pub trait CommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput>;
+
+    /// Run a command that has to succeed
+    fn run_checked(&self, program: &str, args: &[&str]) -> Result<String> {
+        let output = self.run(program, args)?;
+        if !output.success {
+            return Err(service_error(ServiceError::OperationFailed {
+                operation: format!("{} {} ({})", program, args.join(" "), output.stderr.trim()),
+            }));
+        }
+        Ok(output.stdout)
+    }
+}
+
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .with_context(|| format!("cannot run {}", program))?;
+        Ok(CommandOutput {
+            success: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+fn service_error(error: ServiceError) -> anyhow::Error {
+    GhostLinkError::Service(error).into()
+}
+
+/// Init system that will run the agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitSystem {
+    /// `user` for a `systemctl --user` unit
+    Systemd { user: bool },
+    OpenRc,
+    SysVinit,
+}
+
+impl InitSystem {
+    /// Work out the init system of this machine
+    pub fn detect() -> Result<Self> {
+        let root = crate::elevation::sudo::is_root();
+        if Path::new("/run/systemd/system").exists() {
+            return Ok(InitSystem::Systemd { user: !root });
+        }
+
+        let init = if Path::new("/sbin/openrc-run").exists() || Path::new("/run/openrc").exists() {
+            InitSystem::OpenRc
+        } else if Path::new("/etc/init.d").is_dir() {
+            InitSystem::SysVinit
+        } else {
+            return Err(service_error(ServiceError::PlatformNotSupported));
+        };
+        if !root {
+            return Err(service_error(ServiceError::InsufficientPrivileges));
+        }
+        Ok(init)
+    }
+}
+
+/// Everything that goes into a unit file or init script
+#[derive(Debug, Clone)]
+pub struct UnitConfig {
+    pub exe_path: PathBuf,
+    pub server_url: String,
+    pub account: Option<String>,
+    pub protect_system: bool,
+    pub no_new_privileges: bool,
+}
+
+impl UnitConfig {
+    fn new(server_url: &str, options: &InstallOptions) -> Result<Self> {
+        Ok(Self {
+            exe_path: std::env::current_exe()?,
+            server_url: server_url.to_string(),
+            account: options.account.clone(),
+            protect_system: options.protect_system,
+            no_new_privileges: options.no_new_privileges,
+        })
+    }
+}
+
+/// Quote one word of a systemd command line; `%` starts a specifier, so it
+/// is doubled
+fn systemd_quote(word: &str) -> String {
+    let word = word.replace('%', "%%");
+    if !word.is_empty() && !word.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        return word;
+    }
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quote one word for /bin/sh
+fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// systemd unit running `start --server <url>`
+pub fn systemd_unit(config: &UnitConfig, user: bool) -> String {
+    let exec_start = [
+        config.exe_path.to_string_lossy().as_ref(),
+        "start",
+        "--server",
+        &config.server_url,
+    ]
+    .iter()
+    .map(|word| systemd_quote(word))
+    .collect::<Vec<_>>()
+    .join(" ");
+
+    let mut unit = format!(
+        "[Unit]\n\
+         Description={}\n\
+         After=network-online.target\n\
+         Wants=network-online.target\n\
+         StartLimitIntervalSec=0\n\
+         \n\
+         [Service]\n\
+         Type=simple\n\
+         ExecStart={}\n\
+         Restart=always\n\
+         RestartSec=5\n",
+        SERVICE_DESCRIPTION, exec_start
     );
-    
-    let service_path = format!("/etc/systemd/system/{}.service", SERVICE_NAME);
-    
-    // Write service file
-    fs::write(&service_path, service_content)?;
-    
-    // Reload systemd and enable service
-    run_command("systemctl", &["daemon-reload"])?;
-    run_command("systemctl", &["enable", SERVICE_NAME])?;
-    
-    info!("Service installed at: {}", service_path);
-    info!("To start the service: sudo systemctl start {}", SERVICE_NAME);
-    
+    // User units always run as their owner
+    if !user {
+        unit.push_str(&format!("User={}\n", config.account.as_deref().unwrap_or("root")));
+    }
+    if config.protect_system {
+        unit.push_str("ProtectSystem=full\n");
+    }
+    if config.no_new_privileges {
+        unit.push_str("NoNewPrivileges=yes\n");
+    }
+    unit.push_str("StandardOutput=journal\nStandardError=journal\n\n[Install]\n");
+    unit.push_str(if user { "WantedBy=default.target\n" } else { "WantedBy=multi-user.target\n" });
+    unit
+}
+
+fn command_args(config: &UnitConfig) -> String {
+    format!("start --server {}", shell_quote(&config.server_url))
+}
+
+/// OpenRC service script
+pub fn openrc_script(config: &UnitConfig) -> String {
+    format!(
+        "#!/sbin/openrc-run\n\
+         \n\
+         description={}\n\
+         command={}\n\
+         command_args={}\n\
+         command_user={}\n\
+         command_background=true\n\
+         pidfile=\"/run/${{RC_SVCNAME}}.pid\"\n\
+         output_log=\"/var/log/${{RC_SVCNAME}}.log\"\n\
+         error_log=\"/var/log/${{RC_SVCNAME}}.log\"\n\
+         \n\
+         depend() {{\n\
+         \tneed net\n\
+         }}\n",
+        shell_quote(SERVICE_DESCRIPTION),
+        shell_quote(&config.exe_path.to_string_lossy()),
+        shell_quote(&command_args(config)),
+        shell_quote(config.account.as_deref().unwrap_or("root")),
+    )
+}
+
+/// LSB init script for sysvinit
+pub fn sysv_script(config: &UnitConfig) -> String {
+    let run = format!(
+        "{} {}",
+        shell_quote(&config.exe_path.to_string_lossy()),
+        command_args(config)
+    );
+    let run = match config.account.as_deref() {
+        Some(account) if account != "root" => format!("su -s /bin/sh {} -c {}", shell_quote(account), shell_quote(&run)),
+        _ => run,
+    };
+
+    format!(
+        r#"#!/bin/sh
+### BEGIN INIT INFO
+# Provides:          {name}
+# Required-Start:    $network $remote_fs
+# Required-Stop:     $network $remote_fs
+# Default-Start:     2 3 4 5
+# Default-Stop:      0 1 6
+# Short-Description: {description}
+### END INIT INFO
+
+PIDFILE=/var/run/{name}.pid
+LOGFILE=/var/log/{name}.log
+
+is_running() {{
+    [ -f "$PIDFILE" ] && kill -0 "$(cat "$PIDFILE")" 2>/dev/null
+}}
+
+case "$1" in
+    start)
+        is_running && exit 0
+        nohup {run} >>"$LOGFILE" 2>&1 &
+        echo $! >"$PIDFILE"
+        ;;
+    stop)
+        is_running && kill "$(cat "$PIDFILE")"
+        rm -f "$PIDFILE"
+        ;;
+    restart)
+        "$0" stop
+        "$0" start
+        ;;
+    status)
+        if is_running; then echo "running"; else echo "stopped"; exit 3; fi
+        ;;
+    *)
+        echo "Usage: $0 {{start|stop|restart|status}}"
+        exit 2
+        ;;
+esac
+"#,
+        name = SERVICE_NAME,
+        description = SERVICE_DESCRIPTION,
+        run = run,
+    )
+}
+
+/// Parse `systemctl show` properties into a status
+pub fn parse_systemctl_show(output: &str) -> ServiceStatus {
+    let property = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+
+    if property("LoadState") == Some("not-found") {
+        return ServiceStatus::new(ServiceState::NotInstalled);
+    }
+
+    let state = match property("ActiveState") {
+        Some("active") | Some("reloading") => ServiceState::Running,
+        Some("activating") => ServiceState::Starting,
+        Some("deactivating") => ServiceState::Stopping,
+        Some("inactive") => ServiceState::Stopped,
+        Some("failed") => ServiceState::Failed,
+        other => ServiceState::Unknown(other.unwrap_or("no state reported").to_string()),
+    };
+    let since_property = match state {
+        ServiceState::Running | ServiceState::Starting => "ActiveEnterTimestamp",
+        _ => "InactiveEnterTimestamp",
+    };
+
+    ServiceStatus {
+        since: property(since_property).and_then(parse_systemd_timestamp),
+        pid: property("MainPID").and_then(|pid| pid.parse().ok()).filter(|pid| *pid != 0),
+        // Only meaningful once a run has ended
+        last_exit_code: property("ExecMainExitTimestampMonotonic")
+            .filter(|ts| *ts != "0")
+            .and(property("ExecMainStatus"))
+            .and_then(|code| code.parse().ok()),
+        state,
+    }
+}
+
+/// systemctl prints timestamps like `Thu 2024-01-04 10:00:00 CET`, in the
+/// local time zone
+fn parse_systemd_timestamp(value: &str) -> Option<chrono::DateTime<Local>> {
+    let mut words = value.split_whitespace().skip(1);
+    let text = format!("{} {}", words.next()?, words.next()?);
+    let naive = NaiveDateTime::parse_from_str(&text, "%Y-%m-%d %H:%M:%S").ok()?;
+    Local.from_local_datetime(&naive).earliest()
+}
+
+/// Installs and manages the agent with one init system
+pub struct LinuxService<R: CommandRunner> {
+    runner: R,
+    init: InitSystem,
+    /// Where the unit or script goes
+    dir: PathBuf,
+}
+
+impl LinuxService<SystemRunner> {
+    pub fn detect() -> Result<Self> {
+        let init = InitSystem::detect()?;
+        let dir = match init {
+            InitSystem::Systemd { user: false } => PathBuf::from("/etc/systemd/system"),
+            InitSystem::Systemd { user: true } => dirs::config_dir()
+                .context("no config directory for a user unit")?
+                .join("systemd")
+                .join("user"),
+            InitSystem::OpenRc | InitSystem::SysVinit => PathBuf::from("/etc/init.d"),
+        };
+        Ok(Self::new(SystemRunner, init, dir))
+    }
+}
+
+impl<R: CommandRunner> LinuxService<R> {
+    pub fn new(runner: R, init: InitSystem, dir: PathBuf) -> Self {
+        Self { runner, init, dir }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        match self.init {
+            InitSystem::Systemd { .. } => self.dir.join(format!("{}.service", SERVICE_NAME)),
+            InitSystem::OpenRc | InitSystem::SysVinit => self.dir.join(SERVICE_NAME),
+        }
+    }
+
+    /// The unit file or init script for `config`
+    pub fn render(&self, config: &UnitConfig) -> String {
+        match self.init {
+            InitSystem::Systemd { user } => systemd_unit(config, user),
+            InitSystem::OpenRc => openrc_script(config),
+            InitSystem::SysVinit => sysv_script(config),
+        }
+    }
+
+    fn systemctl(&self, args: &[&str]) -> Result<String> {
+        match self.init {
+            InitSystem::Systemd { user: true } => {
+                let args: Vec<&str> = std::iter::once("--user").chain(args.iter().copied()).collect();
+                self.runner.run_checked("systemctl", &args)
+            }
+            _ => self.runner.run_checked("systemctl", args),
+        }
+    }
+
+    pub fn install(&self, config: &UnitConfig, auto_start: bool) -> Result<()> {
+        if matches!(self.init, InitSystem::Systemd { user: true }) && config.account.is_some() {
+            warn!("User units run as their owner; ignoring the service account");
+        }
+
+        let path = self.path();
+        fs::create_dir_all(&self.dir)?;
+        fs::write(&path, self.render(config)).with_context(|| format!("cannot write {}", path.display()))?;
+        info!("Service definition written to {}", path.display());
+
+        match self.init {
+            InitSystem::Systemd { .. } => {
+                self.systemctl(&["daemon-reload"])?;
+                if auto_start {
+                    self.systemctl(&["enable", "--now", SERVICE_NAME])?;
+                } else {
+                    self.systemctl(&["start", SERVICE_NAME])?;
+                }
+            }
+            InitSystem::OpenRc => {
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+                if auto_start {
+                    self.runner.run_checked("rc-update", &["add", SERVICE_NAME, "default"])?;
+                }
+                self.runner.run_checked("rc-service", &[SERVICE_NAME, "start"])?;
+            }
+            InitSystem::SysVinit => {
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+                if auto_start {
+                    self.enable_sysv()?;
+                }
+                self.runner.run_checked(&path.to_string_lossy(), &["start"])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Debian-style `update-rc.d`, or `chkconfig` on Red Hat-style systems
+    fn enable_sysv(&self) -> Result<()> {
+        match self.runner.run("update-rc.d", &[SERVICE_NAME, "defaults"]) {
+            Ok(output) if output.success => Ok(()),
+            _ => self.runner.run_checked("chkconfig", &["--add", SERVICE_NAME]).map(|_| ()),
+        }
+    }
+
+    pub fn uninstall(&self) -> Result<()> {
+        let path = self.path();
+        // Stopping or disabling something already stopped or gone is fine
+        match self.init {
+            InitSystem::Systemd { .. } => {
+                let _ = self.systemctl(&["disable", "--now", SERVICE_NAME]);
+            }
+            InitSystem::OpenRc => {
+                let _ = self.runner.run("rc-service", &[SERVICE_NAME, "stop"]);
+                let _ = self.runner.run("rc-update", &["del", SERVICE_NAME, "default"]);
+            }
+            InitSystem::SysVinit => {
+                if path.exists() {
+                    let _ = self.runner.run(&path.to_string_lossy(), &["stop"]);
+                }
+                if !matches!(self.runner.run("update-rc.d", &[SERVICE_NAME, "remove"]), Ok(output) if output.success) {
+                    let _ = self.runner.run("chkconfig", &["--del", SERVICE_NAME]);
+                }
+            }
+        }
+
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        if let InitSystem::Systemd { .. } = self.init {
+            self.systemctl(&["daemon-reload"])?;
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Result<ServiceStatus> {
+        if let InitSystem::Systemd { .. } = self.init {
+            let output = self.systemctl(&[
+                "show",
+                SERVICE_NAME,
+                "--property=LoadState,ActiveState,ActiveEnterTimestamp,InactiveEnterTimestamp,MainPID,ExecMainStatus,ExecMainExitTimestampMonotonic",
+            ])?;
+            return Ok(parse_systemctl_show(&output));
+        }
+
+        let path = self.path();
+        if !path.exists() {
+            return Ok(ServiceStatus::new(ServiceState::NotInstalled));
+        }
+        let output = match self.init {
+            InitSystem::OpenRc => self.runner.run("rc-service", &[SERVICE_NAME, "status"])?,
+            _ => self.runner.run(&path.to_string_lossy(), &["status"])?,
+        };
+        Ok(ServiceStatus::new(if output.success { ServiceState::Running } else { ServiceState::Stopped }))
+    }
+}
+
+pub fn install_service(server_url: &str, options: &InstallOptions) -> Result<()> {
+    let service = LinuxService::detect()?;
+    let config = UnitConfig::new(server_url, options)?;
+
+    if options.dry_run {
+        println!("# {}", service.path().display());
+        print!("{}", service.render(&config));
+        return Ok(());
+    }
+
+    info!("Installing {} service for AtlasConnect", init_name(service.init));
+    service.install(&config, options.auto_start)?;
+    info!("Service installed and started");
     Ok(())
 }
 
 pub fn uninstall_service() -> Result<()> {
-    info!("Uninstalling systemd service");
-    
-    // Stop and disable service
-    let _ = run_command("systemctl", &["stop", SERVICE_NAME]);
-    let _ = run_command("systemctl", &["disable", SERVICE_NAME]);
-    
-    // Remove service file
-    let service_path = format!("/etc/systemd/system/{}.service", SERVICE_NAME);
-    if std::path::Path::new(&service_path).exists() {
-        fs::remove_file(&service_path)?;
-    }
-    
-    // Reload systemd
-    run_command("systemctl", &["daemon-reload"])?;
-    
+    let service = LinuxService::detect()?;
+    info!("Uninstalling {} service", init_name(service.init));
+    service.uninstall()?;
     info!("Service uninstalled successfully");
     Ok(())
 }
 
-pub fn service_status() -> Result<String> {
-    let output = Command::new("systemctl")
-        .args(&["is-active", SERVICE_NAME])
-        .output()?;
-    
-    if output.status.success() {
-        Ok("Running".to_string())
-    } else {
-        let status_output = Command::new("systemctl")
-            .args(&["status", SERVICE_NAME])
-            .output()?;
-        
-        let status = String::from_utf8_lossy(&status_output.stdout);
-        if status.contains("could not be found") {
-            Ok("Not installed".to_string())
-        } else if status.contains("inactive") {
-            Ok("Stopped".to_string())
-        } else {
-            Ok("Unknown".to_string())
-        }
+pub fn service_status() -> Result<ServiceStatus> {
+    LinuxService::detect()?.status()
+}
+
+fn init_name(init: InitSystem) -> &'static str {
+    match init {
+        InitSystem::Systemd { user: false } => "systemd",
+        InitSystem::Systemd { user: true } => "systemd user",
+        InitSystem::OpenRc => "OpenRC",
+        InitSystem::SysVinit => "sysvinit",
     }
 }
 
-fn run_command(command: &str, args: &[&str]) -> Result<()> {
-    let output = Command::new(command)
-        .args(args)
-        .output()?;
-    
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        error!("Command failed: {} {:?} - {}", command, args, stderr);
-        return Err(anyhow::anyhow!("Command failed: {}", stderr));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockRunner {
+        calls: Mutex<Vec<String>>,
+        /// Commands (program plus args) that fail
+        failing: Vec<String>,
+        stdout: String,
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+            let call = std::iter::once(program).chain(args.iter().copied()).collect::<Vec<_>>().join(" ");
+            let success = !self.failing.contains(&call);
+            self.calls.lock().unwrap().push(call);
+            Ok(CommandOutput {
+                success,
+                stdout: self.stdout.clone(),
+                stderr: String::new(),
+            })
+        }
+    }
+
+    fn config() -> UnitConfig {
+        UnitConfig {
+            exe_path: PathBuf::from("/opt/ghostlink/ghostlink-client"),
+            server_url: "wss://relay.example.com/ws".to_string(),
+            account: None,
+            protect_system: false,
+            no_new_privileges: false,
+        }
+    }
+
+    fn calls(service: &LinuxService<MockRunner>) -> Vec<String> {
+        service.runner.calls.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_system_unit() {
+        let unit = systemd_unit(&config(), false);
+        assert!(unit.contains("ExecStart=/opt/ghostlink/ghostlink-client start --server wss://relay.example.com/ws\n"));
+        assert!(unit.contains("User=root\n"));
+        assert!(unit.contains("Restart=always\n"));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+        // Input injection and elevation need privileges, so hardening is opt-in
+        assert!(!unit.contains("ProtectSystem"));
+        assert!(!unit.contains("NoNewPrivileges"));
+    }
+
+    #[test]
+    fn test_user_unit_with_hardening() {
+        let config = UnitConfig {
+            account: Some("ghostlink".to_string()),
+            protect_system: true,
+            no_new_privileges: true,
+            ..config()
+        };
+        let unit = systemd_unit(&config, true);
+        assert!(!unit.contains("User="));
+        assert!(unit.contains("ProtectSystem=full\n"));
+        assert!(unit.contains("NoNewPrivileges=yes\n"));
+        assert!(unit.contains("WantedBy=default.target\n"));
+
+        let unit = systemd_unit(&config, false);
+        assert!(unit.contains("User=ghostlink\n"));
+    }
+
+    #[test]
+    fn test_exec_start_quoting() {
+        let config = UnitConfig {
+            exe_path: PathBuf::from("/opt/Ghost Link/client"),
+            server_url: "wss://relay.example.com/ws?org=a%20b".to_string(),
+            ..config()
+        };
+        let unit = systemd_unit(&config, false);
+        assert!(unit.contains("ExecStart=\"/opt/Ghost Link/client\" start --server wss://relay.example.com/ws?org=a%%20b\n"));
+        assert_eq!(systemd_quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+    }
+
+    #[test]
+    fn test_init_scripts() {
+        let config = UnitConfig {
+            server_url: "wss://relay.example.com/ws?a=1&b='2'".to_string(),
+            ..config()
+        };
+        let script = openrc_script(&config);
+        assert!(script.starts_with("#!/sbin/openrc-run\n"));
+        assert!(script.contains("command='/opt/ghostlink/ghostlink-client'\n"));
+        assert!(script.contains("command_user='root'\n"));
+
+        let script = sysv_script(&config);
+        assert!(script.contains("# Provides:          atlasconnect-agent\n"));
+        assert!(script.contains(
+            "nohup '/opt/ghostlink/ghostlink-client' start --server 'wss://relay.example.com/ws?a=1&b='\\''2'\\''' >>"
+        ));
+
+        let config = UnitConfig { account: Some("ghostlink".to_string()), ..config };
+        assert!(sysv_script(&config).contains("nohup su -s /bin/sh 'ghostlink' -c "));
+    }
+
+    #[test]
+    fn test_systemd_install_and_uninstall() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = LinuxService::new(MockRunner::default(), InitSystem::Systemd { user: true }, dir.path().to_path_buf());
+
+        service.install(&config(), true).unwrap();
+        let unit = fs::read_to_string(dir.path().join("atlasconnect-agent.service")).unwrap();
+        assert!(unit.contains("WantedBy=default.target"));
+        assert_eq!(
+            calls(&service),
+            vec![
+                "systemctl --user daemon-reload",
+                "systemctl --user enable --now atlasconnect-agent",
+            ]
+        );
+
+        service.uninstall().unwrap();
+        assert!(!dir.path().join("atlasconnect-agent.service").exists());
+        assert_eq!(
+            calls(&service)[2..],
+            [
+                "systemctl --user disable --now atlasconnect-agent",
+                "systemctl --user daemon-reload",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_manual_start_is_not_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = LinuxService::new(MockRunner::default(), InitSystem::Systemd { user: false }, dir.path().to_path_buf());
+        service.install(&config(), false).unwrap();
+        assert_eq!(calls(&service), vec!["systemctl daemon-reload", "systemctl start atlasconnect-agent"]);
+    }
+
+    #[test]
+    fn test_sysv_falls_back_to_chkconfig() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = MockRunner {
+            failing: vec!["update-rc.d atlasconnect-agent defaults".to_string()],
+            ..Default::default()
+        };
+        let service = LinuxService::new(runner, InitSystem::SysVinit, dir.path().to_path_buf());
+        service.install(&config(), true).unwrap();
+
+        let script = dir.path().join("atlasconnect-agent");
+        let mode = fs::metadata(&script).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert_eq!(
+            calls(&service),
+            vec![
+                "update-rc.d atlasconnect-agent defaults".to_string(),
+                "chkconfig --add atlasconnect-agent".to_string(),
+                format!("{} start", script.display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_command_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = MockRunner {
+            failing: vec!["systemctl enable --now atlasconnect-agent".to_string()],
+            ..Default::default()
+        };
+        let service = LinuxService::new(runner, InitSystem::Systemd { user: false }, dir.path().to_path_buf());
+        let err = service.install(&config(), true).unwrap_err();
+        assert!(err.to_string().contains("systemctl enable --now atlasconnect-agent"));
+    }
+
+    #[test]
+    fn test_parse_systemctl_show() {
+        let status = parse_systemctl_show(
+            "LoadState=loaded\n\
+             ActiveState=active\n\
+             ActiveEnterTimestamp=Thu 2024-01-04 10:00:00 UTC\n\
+             InactiveEnterTimestamp=\n\
+             MainPID=4242\n\
+             ExecMainStatus=0\n\
+             ExecMainExitTimestampMonotonic=0\n",
+        );
+        assert_eq!(status.state, ServiceState::Running);
+        assert_eq!(status.pid, Some(4242));
+        assert_eq!(status.last_exit_code, None);
+        let expected = NaiveDateTime::parse_from_str("2024-01-04 10:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(status.since.map(|since| since.naive_local()), Some(expected));
+        assert!(status.uptime().is_some());
+
+        let status = parse_systemctl_show(
+            "LoadState=loaded\n\
+             ActiveState=failed\n\
+             InactiveEnterTimestamp=Thu 2024-01-04 11:30:00 UTC\n\
+             MainPID=0\n\
+             ExecMainStatus=101\n\
+             ExecMainExitTimestampMonotonic=123456789\n",
+        );
+        assert_eq!(status.state, ServiceState::Failed);
+        assert_eq!(status.pid, None);
+        assert_eq!(status.last_exit_code, Some(101));
+        assert!(status.uptime().is_none());
+        assert!(status.to_string().starts_with("Failed since 2024-01-04 11:30:00"));
+
+        let status = parse_systemctl_show("LoadState=not-found\nActiveState=inactive\n");
+        assert_eq!(status, ServiceStatus::new(ServiceState::NotInstalled));
+    }
+
+    #[test]
+    fn test_status_uses_systemctl_show() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = MockRunner {
+            stdout: "LoadState=loaded\nActiveState=inactive\n".to_string(),
+            ..Default::default()
+        };
+        let service = LinuxService::new(runner, InitSystem::Systemd { user: false }, dir.path().to_path_buf());
+        assert_eq!(service.status().unwrap().state, ServiceState::Stopped);
+        assert!(calls(&service)[0].starts_with("systemctl show atlasconnect-agent --property="));
+
+        // Init scripts that were never installed aren't queried
+        let service = LinuxService::new(MockRunner::default(), InitSystem::OpenRc, dir.path().to_path_buf());
+        assert_eq!(service.status().unwrap().state, ServiceState::NotInstalled);
+        assert!(calls(&service).is_empty());
     }
-    
-    Ok(())
 }
@@ -1,4 +1,10 @@
 use anyhow::Result;
+use chrono::{DateTime, Local};
+
+pub mod helper;
+
+#[cfg(target_os = "windows")]
+pub mod console;
 
 #[cfg(target_os = "windows")]
 pub mod windows;
@@ -9,27 +15,127 @@ pub mod linux;
 #[cfg(target_os = "macos")]
 pub mod macos;
 
+/// How the service is registered
+#[derive(Debug, Clone)]
+pub struct InstallOptions {
+    /// Account to run as; `None` is LocalSystem on Windows and root elsewhere
+    pub account: Option<String>,
+    /// Password for `account`; systemd and launchd start services without one
+    #[cfg(windows)]
+    pub password: Option<String>,
+    /// Start at boot rather than on demand
+    pub auto_start: bool,
+    /// Make the OS read-only to the agent (systemd `ProtectSystem=full`)
+    pub protect_system: bool,
+    /// Forbid privilege gains (systemd `NoNewPrivileges`); breaks elevation
+    /// through sudo or pkexec
+    pub no_new_privileges: bool,
+    /// Print what would be installed instead of installing it
+    pub dry_run: bool,
+}
+
+/// State of the installed service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceState {
+    NotInstalled,
+    Running,
+    Starting,
+    Stopping,
+    Stopped,
+    /// Only Windows services can be paused
+    #[cfg_attr(not(windows), allow(dead_code))]
+    Paused,
+    Failed,
+    Unknown(String),
+}
+
+impl std::fmt::Display for ServiceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceState::NotInstalled => write!(f, "Not installed"),
+            ServiceState::Running => write!(f, "Running"),
+            ServiceState::Starting => write!(f, "Starting"),
+            ServiceState::Stopping => write!(f, "Stopping"),
+            ServiceState::Stopped => write!(f, "Stopped"),
+            ServiceState::Paused => write!(f, "Paused"),
+            ServiceState::Failed => write!(f, "Failed"),
+            ServiceState::Unknown(state) => write!(f, "Unknown ({})", state),
+        }
+    }
+}
+
+/// What the service manager reports about the service
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceStatus {
+    pub state: ServiceState,
+    /// When the service entered its current state
+    pub since: Option<DateTime<Local>>,
+    pub pid: Option<u32>,
+    /// Exit code of the last run that ended
+    pub last_exit_code: Option<i32>,
+}
+
+impl ServiceStatus {
+    pub fn new(state: ServiceState) -> Self {
+        Self {
+            state,
+            since: None,
+            pid: None,
+            last_exit_code: None,
+        }
+    }
+
+    /// How long the service has been running
+    pub fn uptime(&self) -> Option<chrono::Duration> {
+        match self.state {
+            ServiceState::Running => self.since.map(|since| Local::now() - since),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ServiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.state)?;
+        if let Some(since) = self.since {
+            write!(f, " since {}", since.format("%Y-%m-%d %H:%M:%S"))?;
+        }
+        if let Some(uptime) = self.uptime() {
+            let minutes = uptime.num_minutes();
+            write!(f, " (up {}d {}h {}m)", minutes / 1440, minutes / 60 % 24, minutes % 60)?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, ", pid {}", pid)?;
+        }
+        if let Some(code) = self.last_exit_code {
+            write!(f, ", last exit code {}", code)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct ServiceManager;
 
 impl ServiceManager {
-    pub fn install(server_url: &str) -> Result<()> {
+    pub fn install(server_url: &str, options: &InstallOptions) -> Result<()> {
         #[cfg(target_os = "windows")]
         {
-            windows::install_service(server_url)
+            windows::install_service(server_url, options)
         }
 
         #[cfg(target_os = "linux")]
         {
-            linux::install_service(server_url)
+            linux::install_service(server_url, options)
         }
 
         #[cfg(target_os = "macos")]
         {
-            macos::install_service(server_url)
+            macos::install_service(server_url, options)
         }
 
         #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
         {
+            let _ = (server_url, options);
             Err(anyhow::anyhow!("Service installation not supported on this platform"))
         }
     }
@@ -56,7 +162,7 @@ impl ServiceManager {
         }
     }
 
-    pub fn status() -> Result<String> {
+    pub fn status() -> Result<ServiceStatus> {
         #[cfg(target_os = "windows")]
         {
             windows::service_status()
@@ -74,7 +180,7 @@ impl ServiceManager {
 
         #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
         {
-            Ok("Not supported".to_string())
+            Ok(ServiceStatus::new(ServiceState::Unknown("not supported on this platform".to_string())))
         }
     }
 }
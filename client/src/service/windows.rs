@@ -0,0 +1,439 @@
+//! Windows service
+//!
+//! The agent registers with the service control manager and runs as
+//! LocalSystem in session 0. Capture and input happen in the console session
+//! through a helper process (see [`super::console`]), which follows fast user
+//! switching and RDP connections via the session change notifications the
+//! service subscribes to. While running as a service, logs go to the
+//! Application event log.
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn, Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use windows::core::{HSTRING, PCWSTR};
+use windows::Win32::Foundation::{ERROR_SERVICE_DOES_NOT_EXIST, HANDLE, PSID};
+use windows::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+    EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+};
+use windows_service::service::{
+    ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+    ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod, ServiceInfo, ServiceStartType, ServiceState,
+    ServiceStatus, ServiceType, SessionChangeReason,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use super::console::{self, SupervisorEvent};
+use super::helper::SessionChange;
+use super::{InstallOptions, ServiceState as AgentState, ServiceStatus as AgentStatus};
+use crate::agent::Agent;
+use crate::config::ClientConfig;
+use crate::policy::PolicyStore;
+use crate::registry::{self, RegistryData, RegistryPath};
+use crate::toolbox::queue::CommandQueue;
+
+const SERVICE_NAME: &str = "AtlasConnectAgent";
+const SERVICE_DISPLAY_NAME: &str = "AtlasConnect Agent";
+const SERVICE_DESCRIPTION: &str = "AtlasConnect Remote Access Agent";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// How long a stop or pause waits for the agent to end its sessions and
+/// deregister; also the wait hint reported to the SCM meanwhile
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+const EVENT_SOURCE_KEY: &str = "HKLM\\SYSTEM\\CurrentControlSet\\Services\\EventLog\\Application\\AtlasConnectAgent";
+/// Ships with the .NET Framework and renders every event id as its message
+/// text, which saves us building a message table resource
+const EVENT_MESSAGE_FILE: &str = "%SystemRoot%\\Microsoft.NET\\Framework64\\v4.0.30319\\EventLogMessages.dll";
+
+pub fn install_service(server_url: &str, options: &InstallOptions) -> Result<()> {
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: if options.auto_start { ServiceStartType::AutoStart } else { ServiceStartType::OnDemand },
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![OsString::from("service"), OsString::from("--server"), OsString::from(server_url)],
+        dependencies: vec![],
+        account_name: options.account.as_ref().map(OsString::from),
+        account_password: options.password.as_ref().map(OsString::from),
+    };
+    if options.dry_run {
+        let arguments: Vec<_> = info.launch_arguments.iter().map(|arg| arg.to_string_lossy()).collect();
+        println!("Service:      {} ({})", SERVICE_NAME, SERVICE_DISPLAY_NAME);
+        println!("Command line: {} {}", info.executable_path.display(), arguments.join(" "));
+        println!("Account:      {}", options.account.as_deref().unwrap_or("LocalSystem"));
+        println!("Start type:   {}", if options.auto_start { "automatic" } else { "manual" });
+        println!("Recovery:     restart after 5s, 30s, 120s");
+        return Ok(());
+    }
+
+    info!("Installing Windows service {}", SERVICE_NAME);
+    if options.account.is_some() {
+        // Only LocalSystem may call WTSQueryUserToken
+        warn!("The session helper needs LocalSystem; with another account only unattended tasks will work");
+    }
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+        .context("cannot open the service control manager (run as administrator)")?;
+
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+        .context("cannot create the service")?;
+    service.set_description(SERVICE_DESCRIPTION)?;
+
+    // Restart after a crash, backing off, and forget failures after a day
+    service.update_failure_actions(ServiceFailureActions {
+        reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+        reboot_msg: None,
+        command: None,
+        actions: Some(vec![
+            ServiceAction { action_type: ServiceActionType::Restart, delay: Duration::from_secs(5) },
+            ServiceAction { action_type: ServiceActionType::Restart, delay: Duration::from_secs(30) },
+            ServiceAction { action_type: ServiceActionType::Restart, delay: Duration::from_secs(120) },
+        ]),
+    })?;
+    // An agent that gives up on the relay exits with an error; restart that too
+    service.set_failure_actions_on_non_crash_failures(true)?;
+
+    if let Err(e) = register_event_source() {
+        warn!("Could not register the event log source: {}", e);
+    }
+
+    if options.auto_start {
+        service.start::<&str>(&[]).context("service installed but failed to start")?;
+        info!("Service {} installed and started", SERVICE_NAME);
+    } else {
+        info!("Service {} installed; start it with: sc start {}", SERVICE_NAME, SERVICE_NAME);
+    }
+    Ok(())
+}
+
+pub fn uninstall_service() -> Result<()> {
+    info!("Uninstalling Windows service {}", SERVICE_NAME);
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+        .context("cannot open the service")?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+        // The SCM only removes the service once it has stopped
+        for _ in 0..30 {
+            if service.query_status()?.current_state == ServiceState::Stopped {
+                break;
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+    service.delete()?;
+
+    if let Some(backend) = registry::platform_backend() {
+        let _ = backend.delete_key(&RegistryPath::parse(EVENT_SOURCE_KEY)?);
+    }
+
+    info!("Service uninstalled successfully");
+    Ok(())
+}
+
+pub fn service_status() -> Result<AgentStatus> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = match manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS) {
+        Ok(service) => service,
+        Err(windows_service::Error::Winapi(e)) if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST.0 as i32) => {
+            return Ok(AgentStatus::new(AgentState::NotInstalled));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let status = service.query_status()?;
+    let state = match status.current_state {
+        ServiceState::Running => AgentState::Running,
+        ServiceState::Stopped => AgentState::Stopped,
+        ServiceState::Paused => AgentState::Paused,
+        ServiceState::StartPending | ServiceState::ContinuePending => AgentState::Starting,
+        ServiceState::StopPending | ServiceState::PausePending => AgentState::Stopping,
+    };
+    let last_exit_code = match (&state, status.exit_code) {
+        (AgentState::Stopped, ServiceExitCode::Win32(code)) => Some(code as i32),
+        (AgentState::Stopped, ServiceExitCode::ServiceSpecific(code)) => Some(code as i32),
+        _ => None,
+    };
+    Ok(AgentStatus {
+        pid: status.process_id,
+        last_exit_code,
+        ..AgentStatus::new(state)
+    })
+}
+
+fn register_event_source() -> Result<()> {
+    let backend = registry::platform_backend().ok_or_else(|| anyhow!("no registry backend"))?;
+    let key = RegistryPath::parse(EVENT_SOURCE_KEY)?;
+    backend.create_key(&key)?;
+    backend.set_value(&key, "EventMessageFile", &RegistryData::ExpandString(EVENT_MESSAGE_FILE.to_string()))?;
+    // Error, warning and information
+    backend.set_value(&key, "TypesSupported", &RegistryData::Dword(7))
+}
+
+/// Commands from the SCM for the service's main loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ServiceCommand {
+    Stop,
+    Pause,
+    Continue,
+}
+
+/// Runtime and configuration handed to `service_main`, which the SCM calls
+/// on a thread of its own
+static SERVICE_CONTEXT: OnceLock<(Handle, ClientConfig)> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hand this process to the SCM; returns once the service has stopped
+pub async fn run(config: ClientConfig) -> Result<()> {
+    SERVICE_CONTEXT
+        .set((Handle::current(), config))
+        .map_err(|_| anyhow!("the service is already running"))?;
+
+    tokio::task::spawn_blocking(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main))
+        .await?
+        .context("not started by the service control manager (use `sc start` or `install`)")?;
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!("Service failed: {:#}", e);
+    }
+}
+
+fn run_service() -> Result<()> {
+    let (runtime, config) = SERVICE_CONTEXT.get().cloned().ok_or_else(|| anyhow!("service context missing"))?;
+    let _guard = runtime.enter();
+
+    let (commands_tx, commands) = mpsc::unbounded_channel();
+    let (_helper, helper_events) = console::spawn_supervisor();
+    let session_events = helper_events.clone();
+
+    let status_handle = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown | ServiceControl::Preshutdown => {
+            let _ = commands_tx.send(ServiceCommand::Stop);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Pause => {
+            let _ = commands_tx.send(ServiceCommand::Pause);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Continue => {
+            let _ = commands_tx.send(ServiceCommand::Continue);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::SessionChange(param) => {
+            let _ = session_events.send(SupervisorEvent::SessionChanged {
+                change: session_change(param.reason),
+                session_id: param.notification.session_id,
+            });
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+
+    report(&status_handle, ServiceState::Running, ServiceExitCode::Win32(0))?;
+    info!("Service started");
+
+    let result = runtime.block_on(control_loop(config, commands, &status_handle));
+    let _ = helper_events.send(SupervisorEvent::Shutdown);
+
+    // A non-zero exit code lets the recovery actions restart us
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    report(&status_handle, ServiceState::Stopped, exit_code)?;
+    result
+}
+
+/// Run the agent until the SCM stops the service; pausing disconnects from
+/// the relay until the service is continued
+async fn control_loop(
+    config: ClientConfig,
+    mut commands: mpsc::UnboundedReceiver<ServiceCommand>,
+    status_handle: &ServiceStatusHandle,
+) -> Result<()> {
+    loop {
+        let mut agent = Agent::new(config.clone())?
+            .with_policy_store(PolicyStore::default_location())
+            .with_command_queue(CommandQueue::open(CommandQueue::default_path()));
+        let shutdown = agent.shutdown_handle();
+        let start = agent.start();
+        tokio::pin!(start);
+        let command = loop {
+            tokio::select! {
+                // The agent reconnects on its own; if it returns, it has given
+                // up or installed an update, and the recovery actions restart it
+                result = &mut start => {
+                    result?;
+                    return Err(anyhow!("agent stopped unexpectedly"));
+                }
+                command = commands.recv() => match command {
+                    Some(ServiceCommand::Continue) => continue,
+                    command => break command.unwrap_or(ServiceCommand::Stop),
+                },
+            }
+        };
+
+        // Keep driving the agent so it ends its sessions and deregisters
+        // before the SCM considers us stopped
+        let pending = match command {
+            ServiceCommand::Stop => ServiceState::StopPending,
+            _ => ServiceState::PausePending,
+        };
+        report(status_handle, pending, ServiceExitCode::Win32(0))?;
+        let _ = shutdown.send(()).await;
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, start).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Agent shutdown failed: {:#}", e),
+            Err(_) => warn!("Agent did not shut down within {:?}", SHUTDOWN_TIMEOUT),
+        }
+
+        if command == ServiceCommand::Stop {
+            info!("Service stopping");
+            return Ok(());
+        }
+
+        report(status_handle, ServiceState::Paused, ServiceExitCode::Win32(0))?;
+        info!("Service paused; disconnected from the relay");
+        loop {
+            match commands.recv().await {
+                Some(ServiceCommand::Continue) => break,
+                Some(ServiceCommand::Pause) => {}
+                Some(ServiceCommand::Stop) | None => return Ok(()),
+            }
+        }
+        report(status_handle, ServiceState::Running, ServiceExitCode::Win32(0))?;
+        info!("Service resumed");
+    }
+}
+
+fn report(status_handle: &ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode) -> Result<()> {
+    let controls_accepted = match state {
+        ServiceState::Stopped | ServiceState::StopPending | ServiceState::PausePending => ServiceControlAccept::empty(),
+        _ => {
+            ServiceControlAccept::STOP
+                | ServiceControlAccept::PAUSE_CONTINUE
+                | ServiceControlAccept::SHUTDOWN
+                | ServiceControlAccept::SESSION_CHANGE
+        }
+    };
+    status_handle.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint: match state {
+            ServiceState::StopPending | ServiceState::PausePending => SHUTDOWN_TIMEOUT,
+            _ => Duration::default(),
+        },
+        process_id: None,
+    })?;
+    Ok(())
+}
+
+fn session_change(reason: SessionChangeReason) -> SessionChange {
+    match reason {
+        SessionChangeReason::ConsoleConnect => SessionChange::ConsoleConnect,
+        SessionChangeReason::ConsoleDisconnect => SessionChange::ConsoleDisconnect,
+        SessionChangeReason::RemoteConnect => SessionChange::RemoteConnect,
+        SessionChangeReason::RemoteDisconnect => SessionChange::RemoteDisconnect,
+        SessionChangeReason::SessionLogon => SessionChange::Logon,
+        SessionChangeReason::SessionLogoff => SessionChange::Logoff,
+        SessionChangeReason::SessionLock => SessionChange::Lock,
+        SessionChangeReason::SessionUnlock => SessionChange::Unlock,
+        _ => SessionChange::Other,
+    }
+}
+
+/// Tracing writer that reports each log line to the Application event log
+pub struct EventLog {
+    source: HANDLE,
+}
+
+// The handle is only ever passed to ReportEventW, which is thread-safe
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    pub fn open() -> Result<Self> {
+        let source = unsafe { RegisterEventSourceW(PCWSTR::null(), &HSTRING::from(SERVICE_NAME)) }
+            .context("RegisterEventSourceW failed")?;
+        Ok(Self { source })
+    }
+
+    fn report(&self, kind: REPORT_EVENT_TYPE, message: &str) {
+        let message = HSTRING::from(message.trim_end());
+        let _ = unsafe {
+            ReportEventW(self.source, kind, 0, 0, PSID::default(), 0, Some(&[PCWSTR(message.as_ptr())]), None)
+        };
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        let _ = unsafe { DeregisterEventSource(self.source) };
+    }
+}
+
+/// One log line, reported when the formatter drops it
+pub struct EventLogWriter<'a> {
+    log: &'a EventLog,
+    kind: REPORT_EVENT_TYPE,
+    buffer: Vec<u8>,
+}
+
+impl Write for EventLogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLogWriter<'_> {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.log.report(self.kind, &String::from_utf8_lossy(&self.buffer));
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+    type Writer = EventLogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventLogWriter { log: self, kind: EVENTLOG_INFORMATION_TYPE, buffer: Vec::new() }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let kind = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        EventLogWriter { log: self, kind, buffer: Vec::new() }
+    }
+}
@@ -0,0 +1,455 @@
+//! Session banners shown to the end user
+//!
+//! The server attaches its branding banner to a session request. A banner
+//! that only informs is shown and the session goes ahead; one that needs
+//! acknowledgment holds the request in a [`BannerGate`] until the user
+//! accepts, so no capture or input is set up before they have agreed. A
+//! decline, or no answer in time, cancels the session with a
+//! [`SessionCancelReason`].
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::error::{GhostLinkError, Result};
+
+/// How long the user has to answer when the server sets no limit
+pub const DEFAULT_ACKNOWLEDGMENT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Banner definition pushed with a session request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionBanner {
+    pub id: String,
+    pub session_id: String,
+    pub title: String,
+    pub message: String,
+    pub company_name: String,
+    /// Colors from the server's branding config
+    pub colors: BannerColors,
+    #[serde(default)]
+    pub security_notice: Option<String>,
+    #[serde(default)]
+    pub acknowledgment_required: bool,
+    #[serde(default)]
+    pub acknowledgment_timeout_secs: Option<u32>,
+    /// How long an informational banner stays up
+    #[serde(default)]
+    pub auto_hide_seconds: Option<u32>,
+}
+
+impl SessionBanner {
+    pub fn acknowledgment_timeout(&self) -> Duration {
+        self.acknowledgment_timeout_secs
+            .map_or(DEFAULT_ACKNOWLEDGMENT_TIMEOUT, |secs| Duration::from_secs(secs.into()))
+    }
+}
+
+/// CSS hex colors, e.g. `#0d6efd`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BannerColors {
+    pub background: String,
+    pub text: String,
+    pub accent: String,
+}
+
+/// Why the agent cancelled a session before it started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionCancelReason {
+    /// The user declined the banner
+    #[serde(rename = "banner_declined")]
+    Declined,
+    /// Nobody answered the banner in time
+    #[serde(rename = "banner_timed_out")]
+    TimedOut,
+    /// The banner could not be shown, so nobody could accept it
+    #[serde(rename = "banner_unavailable")]
+    Unavailable,
+}
+
+impl std::fmt::Display for SessionCancelReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionCancelReason::Declined => write!(f, "the user declined the session banner"),
+            SessionCancelReason::TimedOut => write!(f, "the session banner was not acknowledged in time"),
+            SessionCancelReason::Unavailable => write!(f, "the session banner could not be shown"),
+        }
+    }
+}
+
+/// What came of showing a banner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BannerOutcome {
+    /// Informational banner; the session does not wait for it
+    Shown,
+    Accepted,
+    Cancelled(SessionCancelReason),
+}
+
+/// Where banners are shown; the desktop or the console
+#[async_trait]
+pub trait BannerDisplay: Send + Sync {
+    /// Show a banner that needs no answer
+    async fn notify(&self, banner: &SessionBanner) -> Result<()>;
+
+    /// Show a banner and wait for the user; true when they accept
+    async fn ask(&self, banner: &SessionBanner) -> Result<bool>;
+
+    /// Whether there is anywhere to show a banner, e.g. false for a
+    /// service with no terminal
+    fn available(&self) -> bool {
+        true
+    }
+}
+
+/// Show `banner` and, if it needs acknowledgment, wait for the answer
+pub async fn present(display: &dyn BannerDisplay, banner: &SessionBanner) -> BannerOutcome {
+    if !banner.acknowledgment_required {
+        if let Err(e) = display.notify(banner).await {
+            warn!("Failed to show banner for session {}: {:#}", banner.session_id, e);
+        }
+        return BannerOutcome::Shown;
+    }
+    match tokio::time::timeout(banner.acknowledgment_timeout(), display.ask(banner)).await {
+        Ok(Ok(true)) => BannerOutcome::Accepted,
+        Ok(Ok(false)) => BannerOutcome::Cancelled(SessionCancelReason::Declined),
+        Ok(Err(e)) => {
+            warn!("Failed to ask about banner for session {}: {:#}", banner.session_id, e);
+            BannerOutcome::Cancelled(SessionCancelReason::Unavailable)
+        }
+        Err(_) => BannerOutcome::Cancelled(SessionCancelReason::TimedOut),
+    }
+}
+
+/// The user's answer for a held session
+#[derive(Debug, Clone, PartialEq)]
+pub struct BannerDecision {
+    pub session_id: String,
+    pub banner_id: String,
+    pub outcome: BannerOutcome,
+}
+
+/// Session requests waiting on the end user's answer to their banner
+pub struct BannerGate<T> {
+    display: Arc<dyn BannerDisplay>,
+    held: parking_lot::Mutex<HashMap<String, T>>,
+    decisions: mpsc::Sender<BannerDecision>,
+}
+
+impl<T: Send + 'static> BannerGate<T> {
+    pub fn new(display: Arc<dyn BannerDisplay>) -> (Self, mpsc::Receiver<BannerDecision>) {
+        let (decisions, rx) = mpsc::channel(16);
+        let gate = Self {
+            display,
+            held: parking_lot::Mutex::new(HashMap::new()),
+            decisions,
+        };
+        (gate, rx)
+    }
+
+    /// Show `banner` without holding anything
+    pub fn notify(&self, banner: SessionBanner) {
+        let display = Arc::clone(&self.display);
+        tokio::spawn(async move {
+            present(display.as_ref(), &banner).await;
+        });
+    }
+
+    /// Hold `request` until the user answers `banner`; the answer arrives
+    /// on the receiver from [`BannerGate::new`]
+    pub fn hold(&self, banner: SessionBanner, request: T) {
+        self.held.lock().insert(banner.session_id.clone(), request);
+        let display = Arc::clone(&self.display);
+        let decisions = self.decisions.clone();
+        tokio::spawn(async move {
+            let outcome = present(display.as_ref(), &banner).await;
+            let decision = BannerDecision {
+                session_id: banner.session_id,
+                banner_id: banner.id,
+                outcome,
+            };
+            let _ = decisions.send(decision).await;
+        });
+    }
+
+    /// The request held for a decision, unless the session was cancelled
+    /// while the user was deciding
+    pub fn release(&self, decision: &BannerDecision) -> Option<T> {
+        self.held.lock().remove(&decision.session_id)
+    }
+
+    /// Drop a held request; true if there was one
+    pub fn cancel(&self, session_id: &str) -> bool {
+        self.held.lock().remove(session_id).is_some()
+    }
+
+    pub fn is_held(&self, session_id: &str) -> bool {
+        self.held.lock().contains_key(session_id)
+    }
+
+    pub fn display_available(&self) -> bool {
+        self.display.available()
+    }
+}
+
+/// Banner display on the terminal the agent was started from
+pub struct ConsoleBanner;
+
+impl ConsoleBanner {
+    fn print(banner: &SessionBanner) {
+        let paint = |hex: &str, background: bool| {
+            hex_rgb(hex).map_or_else(String::new, |(r, g, b)| {
+                format!("\x1b[{};2;{};{};{}m", if background { 48 } else { 38 }, r, g, b)
+            })
+        };
+        println!();
+        println!(
+            "{}{} {} — {} \x1b[0m",
+            paint(&banner.colors.background, true),
+            paint(&banner.colors.text, false),
+            banner.company_name,
+            banner.title,
+        );
+        println!("{}", banner.message);
+        if let Some(notice) = &banner.security_notice {
+            println!("{}{}\x1b[0m", paint(&banner.colors.accent, false), notice);
+        }
+    }
+}
+
+#[async_trait]
+impl BannerDisplay for ConsoleBanner {
+    async fn notify(&self, banner: &SessionBanner) -> Result<()> {
+        Self::print(banner);
+        Ok(())
+    }
+
+    async fn ask(&self, banner: &SessionBanner) -> Result<bool> {
+        Self::print(banner);
+        println!("Press Enter to allow this session, or type n and Enter to refuse it.");
+        let mut line = String::new();
+        if BufReader::new(tokio::io::stdin()).read_line(&mut line).await? == 0 {
+            return Err(GhostLinkError::Other("no console to answer on".to_string()));
+        }
+        Ok(!matches!(line.trim().to_lowercase().as_str(), "n" | "no"))
+    }
+
+    fn available(&self) -> bool {
+        std::io::IsTerminal::is_terminal(&std::io::stdin())
+    }
+}
+
+/// Banner display as desktop notifications
+#[cfg(feature = "desktop-banner")]
+pub struct DesktopBanner;
+
+#[cfg(feature = "desktop-banner")]
+impl DesktopBanner {
+    fn notification(banner: &SessionBanner) -> notify_rust::Notification {
+        let mut body = banner.message.clone();
+        if let Some(notice) = &banner.security_notice {
+            body = format!("{}\n\n{}", body, notice);
+        }
+        let mut notification = notify_rust::Notification::new();
+        notification
+            .appname(&banner.company_name)
+            .summary(&banner.title)
+            .body(&body);
+        notification
+    }
+}
+
+#[cfg(feature = "desktop-banner")]
+#[async_trait]
+impl BannerDisplay for DesktopBanner {
+    async fn notify(&self, banner: &SessionBanner) -> Result<()> {
+        let mut notification = Self::notification(banner);
+        if let Some(secs) = banner.auto_hide_seconds {
+            notification.timeout(Duration::from_secs(secs.into()));
+        }
+        notification.show()?;
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    async fn ask(&self, banner: &SessionBanner) -> Result<bool> {
+        let mut notification = Self::notification(banner);
+        notification
+            .action("accept", "Allow")
+            .action("decline", "Refuse")
+            .hint(notify_rust::Hint::Resident(true))
+            .urgency(notify_rust::Urgency::Critical)
+            .timeout(notify_rust::Timeout::Never);
+        let handle = notification.show()?;
+        // Closing the notification without choosing refuses the session
+        let accepted = tokio::task::spawn_blocking(move || {
+            let mut accepted = false;
+            handle.wait_for_action(|action| accepted = action == "accept");
+            accepted
+        });
+        Ok(accepted.await?)
+    }
+
+    // Notifications elsewhere cannot carry buttons
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    async fn ask(&self, banner: &SessionBanner) -> Result<bool> {
+        ConsoleBanner.ask(banner).await
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn available(&self) -> bool {
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    }
+
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    fn available(&self) -> bool {
+        ConsoleBanner.available()
+    }
+}
+
+/// The display for this build: desktop notifications when built with
+/// `desktop-banner`, the console otherwise
+pub fn platform_display() -> Arc<dyn BannerDisplay> {
+    #[cfg(feature = "desktop-banner")]
+    return Arc::new(DesktopBanner);
+    #[cfg(not(feature = "desktop-banner"))]
+    Arc::new(ConsoleBanner)
+}
+
+/// `#rrggbb` as its components
+fn hex_rgb(hex: &str) -> Option<(u8, u8, u8)> {
+    let hex = hex.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let component = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some((component(0)?, component(2)?, component(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    /// Display that answers from a script and remembers what it was shown
+    struct ScriptedDisplay {
+        answer: Option<std::result::Result<bool, String>>,
+        shown: Mutex<Vec<&'static str>>,
+    }
+
+    impl ScriptedDisplay {
+        fn answering(answer: Option<std::result::Result<bool, String>>) -> Arc<Self> {
+            Arc::new(Self { answer, shown: Mutex::new(Vec::new()) })
+        }
+    }
+
+    #[async_trait]
+    impl BannerDisplay for ScriptedDisplay {
+        async fn notify(&self, _banner: &SessionBanner) -> Result<()> {
+            self.shown.lock().push("notify");
+            Ok(())
+        }
+
+        async fn ask(&self, _banner: &SessionBanner) -> Result<bool> {
+            self.shown.lock().push("ask");
+            match &self.answer {
+                Some(Ok(accepted)) => Ok(*accepted),
+                Some(Err(e)) => Err(GhostLinkError::Other(e.to_string())),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    fn banner(session_id: &str, acknowledgment_required: bool) -> SessionBanner {
+        SessionBanner {
+            id: format!("banner-{}", session_id),
+            session_id: session_id.to_string(),
+            title: "Remote Connection Established".to_string(),
+            message: "This session is being recorded.".to_string(),
+            company_name: "GhostLink".to_string(),
+            colors: BannerColors {
+                background: "#0d6efd".to_string(),
+                text: "#ffffff".to_string(),
+                accent: "#198754".to_string(),
+            },
+            security_notice: None,
+            acknowledgment_required,
+            acknowledgment_timeout_secs: Some(30),
+            auto_hide_seconds: Some(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_present_outcomes() {
+        let accept = ScriptedDisplay::answering(Some(Ok(true)));
+        assert_eq!(present(accept.as_ref(), &banner("s1", false)).await, BannerOutcome::Shown);
+        assert_eq!(present(accept.as_ref(), &banner("s1", true)).await, BannerOutcome::Accepted);
+        assert_eq!(*accept.shown.lock(), ["notify", "ask"]);
+
+        let decline = ScriptedDisplay::answering(Some(Ok(false)));
+        let declined = BannerOutcome::Cancelled(SessionCancelReason::Declined);
+        assert_eq!(present(decline.as_ref(), &banner("s1", true)).await, declined);
+
+        let broken = ScriptedDisplay::answering(Some(Err("no display".to_string())));
+        let unavailable = BannerOutcome::Cancelled(SessionCancelReason::Unavailable);
+        assert_eq!(present(broken.as_ref(), &banner("s1", true)).await, unavailable);
+
+        let silent = ScriptedDisplay::answering(None);
+        let mut unanswered = banner("s1", true);
+        unanswered.acknowledgment_timeout_secs = Some(1);
+        let timed_out = BannerOutcome::Cancelled(SessionCancelReason::TimedOut);
+        assert_eq!(present(silent.as_ref(), &unanswered).await, timed_out);
+    }
+
+    #[tokio::test]
+    async fn test_gate_holds_requests_until_answered() {
+        let (gate, mut decisions) = BannerGate::new(ScriptedDisplay::answering(Some(Ok(true))));
+        gate.hold(banner("s1", true), "request-1");
+        assert!(gate.is_held("s1"));
+
+        let decision = decisions.recv().await.unwrap();
+        assert_eq!((decision.banner_id.as_str(), decision.outcome), ("banner-s1", BannerOutcome::Accepted));
+        assert_eq!(gate.release(&decision), Some("request-1"));
+        assert!(!gate.is_held("s1"));
+    }
+
+    #[tokio::test]
+    async fn test_gate_drops_sessions_cancelled_while_waiting() {
+        let (gate, mut decisions) = BannerGate::new(ScriptedDisplay::answering(Some(Ok(false))));
+        gate.hold(banner("s1", true), "request-1");
+        gate.hold(banner("s2", true), "request-2");
+        assert!(gate.cancel("s1"));
+        assert!(!gate.cancel("s1"));
+
+        let mut released = Vec::new();
+        for _ in 0..2 {
+            let decision = decisions.recv().await.unwrap();
+            assert_eq!(decision.outcome, BannerOutcome::Cancelled(SessionCancelReason::Declined));
+            released.push(gate.release(&decision));
+        }
+        released.sort();
+        assert_eq!(released, [None, Some("request-2")]);
+    }
+
+    #[test]
+    fn test_banner_wire_format() {
+        let json = serde_json::json!({
+            "id": "b1",
+            "session_id": "s1",
+            "title": "Heads up",
+            "message": "Recorded",
+            "company_name": "GhostLink",
+            "colors": { "background": "#dc3545", "text": "#ffffff", "accent": "#ffc107" },
+            "acknowledgment_required": true,
+        });
+        let banner: SessionBanner = serde_json::from_value(json).unwrap();
+        assert_eq!(banner.acknowledgment_timeout(), DEFAULT_ACKNOWLEDGMENT_TIMEOUT);
+        assert_eq!(serde_json::json!(SessionCancelReason::TimedOut), "banner_timed_out");
+        assert_eq!(hex_rgb(&banner.colors.background), Some((0xdc, 0x35, 0x45)));
+        assert_eq!(hex_rgb("red"), None);
+    }
+}
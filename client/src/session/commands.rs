@@ -0,0 +1,403 @@
+//! Commands tab: modifiers, results and their history
+//!
+//! Technicians prefix a command with ScreenConnect-style modifiers:
+//!
+//! ```text
+//! #timeout 60
+//! #maxlength 65536
+//! #!powershell
+//! Get-Service | Where-Object Status -eq Running
+//! ```
+//!
+//! Modifiers may come in any order, on their own lines or on the command's
+//! line, and the first text that is not a modifier starts the command. Every
+//! result is appended to a per-session history file so the tab survives a
+//! restart of the helper, and is uploaded to the server for the web timeline.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::error::{Context, GhostLinkError, Result};
+
+/// Timeout when the command has no `#timeout`, as in ScreenConnect
+pub const DEFAULT_TIMEOUT_SECS: u32 = 10;
+/// Output kept when the command has no `#maxlength`
+pub const DEFAULT_MAX_LENGTH: usize = 8 * 1024;
+pub const MAX_TIMEOUT_SECS: u32 = 60 * 60;
+pub const MAX_LENGTH_LIMIT: usize = 1024 * 1024;
+
+/// A command with its modifiers stripped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedCommand {
+    pub command: String,
+    pub timeout_seconds: Option<u32>,
+    pub max_length: Option<usize>,
+    pub shell: Option<String>,
+}
+
+/// Split leading `#timeout N`, `#maxlength N` and `#!shell` modifiers from
+/// `text`. A modifier given twice keeps its last value.
+pub fn parse_command(text: &str) -> Result<ParsedCommand> {
+    let mut parsed = ParsedCommand::default();
+    let mut rest = text.trim_start();
+
+    loop {
+        if let Some(after) = directive(rest, "#timeout") {
+            let (value, remaining) = value(after, "#timeout")?;
+            parsed.timeout_seconds = Some(bounded(value, "#timeout", MAX_TIMEOUT_SECS)?);
+            rest = remaining;
+        } else if let Some(after) = directive(rest, "#maxlength") {
+            let (value, remaining) = value(after, "#maxlength")?;
+            parsed.max_length = Some(bounded(value, "#maxlength", MAX_LENGTH_LIMIT)?);
+            rest = remaining;
+        } else if let Some(after) = rest.strip_prefix("#!") {
+            let (shell, remaining) = value(after, "#!")?;
+            parsed.shell = Some(shell.to_string());
+            rest = remaining;
+        } else {
+            break;
+        }
+        rest = rest.trim_start();
+    }
+
+    if rest.trim().is_empty() {
+        return Err(GhostLinkError::Other("No command given after the modifiers".to_string()));
+    }
+    parsed.command = rest.trim_end().to_string();
+    Ok(parsed)
+}
+
+/// Text after `name` when `rest` starts with that modifier as a whole word
+fn directive<'a>(rest: &'a str, name: &str) -> Option<&'a str> {
+    let head = rest.get(..name.len())?;
+    let after = &rest[name.len()..];
+    let whole_word = after.chars().next().is_none_or(char::is_whitespace);
+    (head.eq_ignore_ascii_case(name) && whole_word).then_some(after)
+}
+
+/// The modifier's value, which has to be on the modifier's own line
+fn value<'a>(after: &'a str, name: &str) -> Result<(&'a str, &'a str)> {
+    let after = after.trim_start_matches([' ', '\t']);
+    let end = after.find(char::is_whitespace).unwrap_or(after.len());
+    if end == 0 {
+        return Err(GhostLinkError::Other(format!("{} needs a value", name)));
+    }
+    Ok(after.split_at(end))
+}
+
+fn bounded<T>(value: &str, name: &str, max: T) -> Result<T>
+where
+    T: std::str::FromStr + PartialOrd + Default + std::fmt::Display,
+{
+    let parsed: T = value.parse().map_err(|_| GhostLinkError::Other(format!("Invalid {} value: {}", name, value)))?;
+    if parsed <= T::default() || parsed > max {
+        return Err(GhostLinkError::Other(format!("{} must be between 1 and {}", name, max)));
+    }
+    Ok(parsed)
+}
+
+/// Cut stdout, then stderr, so together they stay within `max_length`
+/// bytes. Returns whether anything was cut.
+pub fn truncate_output(stdout: &mut String, stderr: &mut String, max_length: usize) -> bool {
+    let truncated = truncate_at(stdout, max_length);
+    truncate_at(stderr, max_length - stdout.len()) || truncated
+}
+
+fn truncate_at(text: &mut String, limit: usize) -> bool {
+    if text.len() <= limit {
+        return false;
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    true
+}
+
+/// One command or tool run from the Commands tab
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandExecution {
+    pub id: Uuid,
+    pub command: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    pub execution_time: chrono::DateTime<chrono::Utc>,
+    pub duration_ms: u64,
+    pub timeout_seconds: u32,
+    pub max_length: usize,
+    pub shell: Option<String>,
+    /// Technician who ran it
+    #[serde(default)]
+    pub executed_by: String,
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default)]
+    pub timed_out: bool,
+    #[serde(default)]
+    pub cancelled: bool,
+}
+
+impl CommandExecution {
+    /// Output as the tab shows it, with markers for cut or stopped runs
+    pub fn output(&self) -> String {
+        let mut output = format!("{}{}", self.stdout, self.stderr);
+        if self.truncated {
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.push('\n');
+            }
+            output.push_str(&format!("[output truncated at {} bytes]\n", self.max_length));
+        }
+        if self.timed_out {
+            output.push_str(&format!("[timed out after {}s]\n", self.timeout_seconds));
+        }
+        if self.cancelled {
+            output.push_str("[cancelled]\n");
+        }
+        output
+    }
+}
+
+/// A session's command results, one JSON object per line
+pub struct CommandHistory {
+    path: PathBuf,
+}
+
+impl CommandHistory {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// `<config dir>/ghostlink/command-history/<session>.jsonl`
+    pub fn for_session(session_id: &str) -> Self {
+        let dir = dirs::config_dir().unwrap_or_else(std::env::temp_dir);
+        let file = format!("{}.jsonl", sanitize(session_id));
+        Self::new(dir.join("ghostlink").join("command-history").join(file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every stored result, oldest first; lines that no longer parse are skipped
+    pub async fn load(&self) -> Result<Vec<CommandExecution>> {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.path.display())),
+        };
+        Ok(contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(execution) => Some(execution),
+                Err(e) => {
+                    warn!("Skipping unreadable command history entry in {}: {}", self.path.display(), e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub async fn append(&self, execution: &CommandExecution) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut line = serde_json::to_vec(execution)?;
+        line.push(b'\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        file.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+/// Session ids come from the server, but never let one leave the directory
+fn sanitize(session_id: &str) -> String {
+    session_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Send a result to the server's session timeline
+pub async fn upload(server_url: &str, session_id: &str, auth_token: &str, execution: &CommandExecution) -> Result<()> {
+    let url = format!("{}/api/sessions/{}/commands", server_url.trim_end_matches('/'), session_id);
+    let response = crate::connection::proxy::http_client()
+        .post(url)
+        .bearer_auth(auth_token)
+        .json(execution)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error: serde_json::Value = response.json().await.unwrap_or_default();
+        return Err(GhostLinkError::Other(format!("Command upload failed ({}): {}", status, error["error"].as_str().unwrap_or("unknown error"))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execution(command: &str) -> CommandExecution {
+        CommandExecution {
+            id: Uuid::new_v4(),
+            command: command.to_string(),
+            stdout: "out\n".to_string(),
+            stderr: String::new(),
+            exit_code: Some(0),
+            execution_time: chrono::Utc::now(),
+            duration_ms: 12,
+            timeout_seconds: DEFAULT_TIMEOUT_SECS,
+            max_length: DEFAULT_MAX_LENGTH,
+            shell: None,
+            executed_by: "tech@example.com".to_string(),
+            truncated: false,
+            timed_out: false,
+            cancelled: false,
+        }
+    }
+
+    #[test]
+    fn test_modifiers_in_any_order() {
+        let parsed = parse_command("#timeout 30\n#maxlength 16384\n#!cmd\nsysteminfo").unwrap();
+        assert_eq!(parsed, ParsedCommand {
+            command: "systeminfo".to_string(),
+            timeout_seconds: Some(30),
+            max_length: Some(16384),
+            shell: Some("cmd".to_string()),
+        });
+
+        let parsed = parse_command("#!pwsh #maxlength 100 #timeout 5 Get-Process | Select -First 3").unwrap();
+        assert_eq!(parsed.shell.as_deref(), Some("pwsh"));
+        assert_eq!(parsed.max_length, Some(100));
+        assert_eq!(parsed.timeout_seconds, Some(5));
+        assert_eq!(parsed.command, "Get-Process | Select -First 3");
+
+        // Last one wins, and names are case-insensitive
+        let parsed = parse_command("#TIMEOUT 5\n#timeout 7\nuptime").unwrap();
+        assert_eq!(parsed.timeout_seconds, Some(7));
+    }
+
+    #[test]
+    fn test_plain_commands_and_comments() {
+        let parsed = parse_command("  ipconfig /all\n").unwrap();
+        assert_eq!(parsed, ParsedCommand { command: "ipconfig /all".to_string(), ..Default::default() });
+
+        // Only leading modifiers count; anything else starting with # is the command
+        let parsed = parse_command("#timeout 5\n# list files\nls\n#timeout 9").unwrap();
+        assert_eq!(parsed.timeout_seconds, Some(5));
+        assert_eq!(parsed.command, "# list files\nls\n#timeout 9");
+        assert_eq!(parse_command("#timeouts 5").unwrap().command, "#timeouts 5");
+    }
+
+    #[test]
+    fn test_invalid_modifiers() {
+        for text in [
+            "#timeout",
+            "#timeout\nls",
+            "#timeout  \nls",
+            "#maxlength\n#timeout 5\nls",
+            "#!\nls",
+            "#! ls",
+            "#timeout abc ls",
+            "#timeout -1 ls",
+            "#timeout 0 ls",
+            "#maxlength 0 ls",
+            "#timeout 3601 ls",
+            "#maxlength 1048577 ls",
+            "#timeout 30",
+            "#timeout 30\n#!bash\n  \n",
+            "",
+        ] {
+            assert!(parse_command(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_truncate_output() {
+        let (mut stdout, mut stderr) = ("hello".to_string(), "world".to_string());
+        assert!(!truncate_output(&mut stdout, &mut stderr, 10));
+        assert_eq!((stdout.as_str(), stderr.as_str()), ("hello", "world"));
+
+        assert!(truncate_output(&mut stdout, &mut stderr, 7));
+        assert_eq!((stdout.as_str(), stderr.as_str()), ("hello", "wo"));
+
+        let (mut stdout, mut stderr) = ("0123456789".to_string(), "error".to_string());
+        assert!(truncate_output(&mut stdout, &mut stderr, 4));
+        assert_eq!((stdout.as_str(), stderr.as_str()), ("0123", ""));
+
+        // Never splits a character
+        let (mut stdout, mut stderr) = ("aé€".to_string(), String::new());
+        assert!(truncate_output(&mut stdout, &mut stderr, 4));
+        assert_eq!(stdout, "aé");
+    }
+
+    #[test]
+    fn test_output_markers() {
+        let mut run = execution("ping host");
+        assert_eq!(run.output(), "out\n");
+
+        run.stdout = "partial".to_string();
+        run.stderr = String::new();
+        run.max_length = 7;
+        run.truncated = true;
+        run.timed_out = true;
+        run.timeout_seconds = 5;
+        assert_eq!(run.output(), "partial\n[output truncated at 7 bytes]\n[timed out after 5s]\n");
+    }
+
+    #[tokio::test]
+    async fn test_history_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = CommandHistory::new(dir.path().join("nested").join("session.jsonl"));
+        assert!(history.load().await.unwrap().is_empty());
+
+        let (first, second) = (execution("whoami"), execution("hostname"));
+        history.append(&first).await.unwrap();
+        history.append(&second).await.unwrap();
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(history.path())
+            .await
+            .unwrap()
+            .write_all(b"{not json\n")
+            .await
+            .unwrap();
+
+        assert_eq!(history.load().await.unwrap(), vec![first, second]);
+        assert!(CommandHistory::for_session("../../etc/passwd").path().ends_with("command-history/______etc_passwd.jsonl"));
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/sessions/abc/commands"))
+            .and(header("authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&server)
+            .await;
+        upload(&server.uri(), "abc", "token", &execution("whoami")).await.unwrap();
+
+        let error = upload(&server.uri(), "other", "token", &execution("whoami")).await.unwrap_err();
+        assert!(error.to_string().contains("404"), "{}", error);
+    }
+}
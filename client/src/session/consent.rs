@@ -0,0 +1,186 @@
+//! Consent prompts for attended sessions
+//!
+//! When policy requires it, the server marks a session request
+//! `requires_consent` and the agent asks the person at the device before
+//! anything is captured. The prompt is an acknowledgment banner held in the
+//! agent's [`BannerGate`](super::banner::BannerGate), so the console and
+//! desktop displays that show banners show it too. A prompt nobody answers,
+//! or a device with no display to show it on, is settled by the policy's
+//! [`ConsentTimeoutAction`].
+
+use std::time::Duration;
+
+use super::banner::{BannerColors, BannerOutcome, SessionBanner, SessionCancelReason};
+use crate::policy::{ConsentTimeoutAction, ServerPolicy};
+
+/// Banner id of consent prompts; they are the agent's, not the server's
+pub const CONSENT_BANNER_ID: &str = "consent";
+
+/// How long the prompt waits and what silence means
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsentPolicy {
+    pub timeout: Duration,
+    pub on_timeout: ConsentTimeoutAction,
+}
+
+impl ConsentPolicy {
+    pub fn from_policy(policy: &ServerPolicy) -> Self {
+        Self {
+            timeout: policy.consent_timeout(),
+            on_timeout: policy.consent_timeout_action(),
+        }
+    }
+
+    /// The prompt asking whether `requester` may start the session
+    pub fn prompt(&self, session_id: &str, session_type: &str, requester: &str) -> SessionBanner {
+        let fallback = match self.on_timeout {
+            ConsentTimeoutAction::Accept => "allowed",
+            ConsentTimeoutAction::Decline => "refused",
+        };
+        SessionBanner {
+            id: CONSENT_BANNER_ID.to_string(),
+            session_id: session_id.to_string(),
+            title: "Allow remote access?".to_string(),
+            message: format!(
+                "{} wants to start a {} session on this computer. Without an answer in {} seconds it will be {}.",
+                requester,
+                session_type,
+                self.timeout.as_secs(),
+                fallback,
+            ),
+            company_name: "GhostLink".to_string(),
+            colors: BannerColors {
+                background: "#0d6efd".to_string(),
+                text: "#ffffff".to_string(),
+                accent: "#ffc107".to_string(),
+            },
+            security_notice: None,
+            acknowledgment_required: true,
+            acknowledgment_timeout_secs: Some(self.timeout.as_secs().try_into().unwrap_or(u32::MAX)),
+            auto_hide_seconds: None,
+        }
+    }
+
+    /// What showing the prompt came to
+    pub fn settle(&self, outcome: BannerOutcome) -> ConsentOutcome {
+        match outcome {
+            BannerOutcome::Accepted | BannerOutcome::Shown => ConsentOutcome::Accepted,
+            BannerOutcome::Cancelled(SessionCancelReason::Declined) => ConsentOutcome::Declined,
+            BannerOutcome::Cancelled(SessionCancelReason::TimedOut) => ConsentOutcome::TimedOut(self.on_timeout),
+            BannerOutcome::Cancelled(SessionCancelReason::Unavailable) => ConsentOutcome::Headless(self.on_timeout),
+        }
+    }
+}
+
+/// The answer to a consent prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentOutcome {
+    Accepted,
+    Declined,
+    /// Nobody answered in time, so the policy decided
+    TimedOut(ConsentTimeoutAction),
+    /// There was no display to ask on, so the policy decided
+    Headless(ConsentTimeoutAction),
+}
+
+impl ConsentOutcome {
+    pub fn accepted(&self) -> bool {
+        matches!(
+            self,
+            ConsentOutcome::Accepted
+                | ConsentOutcome::TimedOut(ConsentTimeoutAction::Accept)
+                | ConsentOutcome::Headless(ConsentTimeoutAction::Accept)
+        )
+    }
+}
+
+/// Reason sent back in the session response
+impl std::fmt::Display for ConsentOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let by_policy = |action: &ConsentTimeoutAction| match action {
+            ConsentTimeoutAction::Accept => "accepted",
+            ConsentTimeoutAction::Decline => "declined",
+        };
+        match self {
+            ConsentOutcome::Accepted => write!(f, "the user accepted the session"),
+            ConsentOutcome::Declined => write!(f, "the user declined the session"),
+            ConsentOutcome::TimedOut(action) => {
+                write!(f, "nobody answered the consent prompt; {} by policy", by_policy(action))
+            }
+            ConsentOutcome::Headless(action) => {
+                write!(f, "no display to ask for consent on; {} by policy", by_policy(action))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::banner::{present, BannerDisplay};
+    use crate::error::Result;
+    use async_trait::async_trait;
+
+    /// Dialog that answers with `Some`, never answers with `None`
+    struct MockDialog(Option<bool>);
+
+    #[async_trait]
+    impl BannerDisplay for MockDialog {
+        async fn notify(&self, _banner: &SessionBanner) -> Result<()> {
+            Ok(())
+        }
+
+        async fn ask(&self, _banner: &SessionBanner) -> Result<bool> {
+            match self.0 {
+                Some(accepted) => Ok(accepted),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    fn policy(on_timeout: ConsentTimeoutAction) -> ConsentPolicy {
+        ConsentPolicy { timeout: Duration::from_secs(1), on_timeout }
+    }
+
+    async fn ask(dialog: MockDialog, policy: ConsentPolicy) -> ConsentOutcome {
+        let prompt = policy.prompt("s1", "console", "alice@example.com");
+        policy.settle(present(&dialog, &prompt).await)
+    }
+
+    #[tokio::test]
+    async fn test_answered_prompts() {
+        let outcome = ask(MockDialog(Some(true)), policy(ConsentTimeoutAction::Decline)).await;
+        assert_eq!(outcome, ConsentOutcome::Accepted);
+        assert!(outcome.accepted());
+
+        let outcome = ask(MockDialog(Some(false)), policy(ConsentTimeoutAction::Accept)).await;
+        assert_eq!(outcome, ConsentOutcome::Declined);
+        assert!(!outcome.accepted());
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_prompts_follow_policy() {
+        let outcome = ask(MockDialog(None), policy(ConsentTimeoutAction::Accept)).await;
+        assert_eq!(outcome, ConsentOutcome::TimedOut(ConsentTimeoutAction::Accept));
+        assert!(outcome.accepted());
+        assert_eq!(outcome.to_string(), "nobody answered the consent prompt; accepted by policy");
+
+        let outcome = ask(MockDialog(None), policy(ConsentTimeoutAction::Decline)).await;
+        assert_eq!(outcome, ConsentOutcome::TimedOut(ConsentTimeoutAction::Decline));
+        assert!(!outcome.accepted());
+    }
+
+    #[test]
+    fn test_prompt_names_requester_and_session_type() {
+        let consent = ConsentPolicy::from_policy(&ServerPolicy::default());
+        let prompt = consent.prompt("s1", "console", "alice@example.com");
+        assert_eq!(prompt.acknowledgment_timeout(), Duration::from_secs(30));
+        assert!(prompt.acknowledgment_required);
+        assert!(prompt.message.starts_with("alice@example.com wants to start a console session"));
+        assert!(prompt.message.ends_with("in 30 seconds it will be refused."));
+
+        let headless = consent.settle(BannerOutcome::Cancelled(SessionCancelReason::Unavailable));
+        assert_eq!(headless, ConsentOutcome::Headless(ConsentTimeoutAction::Decline));
+        assert!(!headless.accepted());
+    }
+}
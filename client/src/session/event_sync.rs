@@ -0,0 +1,264 @@
+//! Upload of timeline events to the server
+//!
+//! The session window emits events without waiting on the network. A task
+//! collects them and posts them in batches to `/api/sessions/:id/events`;
+//! while the server is unreachable it keeps them and retries with backoff,
+//! so a technician working through a connection drop still ends up with a
+//! complete timeline on the web. Retried batches may reach the server twice,
+//! which it recognises by the events' IDs.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::error::{GhostLinkError, Result};
+
+use super::events::{SessionEvent, SessionEventKind};
+
+#[derive(Debug, Clone)]
+pub struct EventSyncConfig {
+    /// Events per request
+    pub batch_size: usize,
+    /// How long an event may wait for others to share its request
+    pub flush_interval: Duration,
+    /// Wait after the first failed upload; doubles per failure up to `max_backoff`
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Events kept while offline; the oldest are dropped beyond this
+    pub max_pending: usize,
+}
+
+impl Default for EventSyncConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 50,
+            flush_interval: Duration::from_secs(2),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_pending: 10_000,
+        }
+    }
+}
+
+/// Handle the session window emits events through; cheap to clone
+#[derive(Clone)]
+pub struct EventSender {
+    session_id: Uuid,
+    sequence: Arc<AtomicU64>,
+    tx: mpsc::UnboundedSender<SessionEvent>,
+}
+
+impl EventSender {
+    /// Start uploading events of `session_id`; the upload ends with a last
+    /// attempt once every sender is dropped
+    pub fn spawn(server_url: &str, session_id: Uuid, auth_token: String, config: EventSyncConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let uploader = EventUploader {
+            client: crate::connection::proxy::http_client(),
+            url: format!("{}/api/sessions/{}/events", server_url.trim_end_matches('/'), session_id),
+            auth_token,
+            pending: VecDeque::new(),
+            config,
+        };
+        tokio::spawn(uploader.run(rx));
+        Self { session_id, sequence: Arc::new(AtomicU64::new(0)), tx }
+    }
+
+    /// Queue an event for upload and return it
+    pub fn emit(&self, kind: SessionEventKind, actor: &str, details: serde_json::Value) -> SessionEvent {
+        let mut event = SessionEvent::new(self.session_id, kind, actor, details);
+        event.sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.tx.send(event.clone()).is_err() {
+            debug!("Event upload of session {} has stopped", self.session_id);
+        }
+        event
+    }
+}
+
+struct EventUploader {
+    client: reqwest::Client,
+    url: String,
+    auth_token: String,
+    pending: VecDeque<SessionEvent>,
+    config: EventSyncConfig,
+}
+
+impl EventUploader {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<SessionEvent>) {
+        // When the next upload is due, while events are pending
+        let mut flush_at: Option<Instant> = None;
+        let mut backoff: Option<Duration> = None;
+
+        loop {
+            if backoff.is_none() && self.pending.len() >= self.config.batch_size {
+                flush_at = Some(Instant::now());
+            }
+            let due = flush_at;
+
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Some(event) => {
+                        self.enqueue(event);
+                        flush_at.get_or_insert_with(|| Instant::now() + self.config.flush_interval);
+                    }
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                    match self.flush().await {
+                        Ok(()) => {
+                            backoff = None;
+                            flush_at = None;
+                        }
+                        Err(e) => {
+                            let delay = backoff.map_or(self.config.initial_backoff, |previous| {
+                                (previous * 2).min(self.config.max_backoff)
+                            });
+                            warn!("Failed to upload {} timeline events, retrying in {:?}: {}", self.pending.len(), delay, e);
+                            backoff = Some(delay);
+                            flush_at = Some(Instant::now() + delay);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = self.flush().await {
+            warn!("Dropping {} timeline events that could not be uploaded: {}", self.pending.len(), e);
+        }
+    }
+
+    fn enqueue(&mut self, event: SessionEvent) {
+        if self.pending.len() >= self.config.max_pending {
+            self.pending.pop_front();
+            warn!("Timeline upload is backed up; dropped the oldest event");
+        }
+        self.pending.push_back(event);
+    }
+
+    /// Upload every pending event, a batch at a time
+    async fn flush(&mut self) -> Result<()> {
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(self.config.batch_size);
+            let batch: Vec<&SessionEvent> = self.pending.iter().take(count).collect();
+            let response = self.client
+                .post(&self.url)
+                .bearer_auth(&self.auth_token)
+                .json(&serde_json::json!({ "events": batch }))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(GhostLinkError::Other(format!("server answered {}", response.status())));
+            }
+            self.pending.drain(..count);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn config() -> EventSyncConfig {
+        EventSyncConfig {
+            batch_size: 3,
+            flush_interval: Duration::from_millis(50),
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(80),
+            max_pending: 100,
+        }
+    }
+
+    /// Events the server received, per request, once `requests` arrived
+    async fn batches(server: &MockServer, requests: usize) -> Vec<Vec<SessionEvent>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let received = server.received_requests().await.unwrap();
+            if received.len() >= requests || Instant::now() > deadline {
+                return received
+                    .iter()
+                    .map(|request| {
+                        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                        serde_json::from_value(body["events"].clone()).unwrap()
+                    })
+                    .collect();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_are_batched_in_order() {
+        let server = MockServer::start().await;
+        let session_id = Uuid::new_v4();
+        Mock::given(method("POST"))
+            .and(path(format!("/api/sessions/{}/events", session_id)))
+            .and(header("authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let sender = EventSender::spawn(&server.uri(), session_id, "token".to_string(), config());
+        let emitted: Vec<SessionEvent> = (0..7)
+            .map(|i| sender.emit(SessionEventKind::Note, "tech@example.com", serde_json::json!({ "index": i })))
+            .collect();
+
+        // Two full batches right away, the rest after the flush interval
+        let received = batches(&server, 3).await;
+        assert_eq!(received.iter().map(Vec::len).collect::<Vec<_>>(), vec![3, 3, 1]);
+        assert_eq!(received.concat(), emitted);
+        assert_eq!(emitted.iter().map(|event| event.sequence).collect::<Vec<_>>(), (1..=7).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_failed_uploads_are_retried() {
+        let server = MockServer::start().await;
+        let session_id = Uuid::new_v4();
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let sender = EventSender::spawn(&server.uri(), session_id, "token".to_string(), config());
+        let first = sender.emit(SessionEventKind::Command, "tech@example.com", serde_json::json!({}));
+        let second = sender.emit(SessionEventKind::TabSwitch, "tech@example.com", serde_json::json!({}));
+
+        let received = batches(&server, 3).await;
+        assert_eq!(received.len(), 3);
+        assert!(received.iter().all(|batch| batch == &vec![first.clone(), second.clone()]));
+
+        // Nothing is sent again once the upload went through
+        let third = sender.emit(SessionEventKind::Note, "tech@example.com", serde_json::json!({}));
+        assert_eq!(batches(&server, 4).await[3], vec![third]);
+    }
+
+    #[tokio::test]
+    async fn test_pending_events_are_sent_on_shutdown() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut config = config();
+        config.flush_interval = Duration::from_secs(60);
+        let sender = EventSender::spawn(&server.uri(), Uuid::new_v4(), "token".to_string(), config);
+        let event = sender.emit(SessionEventKind::BackstageToggle, "tech@example.com", serde_json::json!({}));
+        drop(sender);
+
+        assert_eq!(batches(&server, 1).await, vec![vec![event]]);
+    }
+}
@@ -0,0 +1,618 @@
+//! Remote input indicator
+//!
+//! While a technician's input reaches this machine, a small window in the
+//! corner of the screen names them. It is always on top and lets clicks
+//! through, so it never gets in the user's way, and it cannot be dismissed:
+//! it goes away when input stops reaching the machine, because the session
+//! ended, input was blocked or the technician switched to preview. The agent
+//! re-evaluates its sessions every [`POLL_INTERVAL`], so the indicator is
+//! gone within a second of the session ending.
+//!
+//! Backstage sessions run without telling the user and show no indicator,
+//! unless `backstage` is set or the server's `input_indicator_required`
+//! policy forces it on for every session.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::error::Result;
+use crate::session::SessionType;
+
+/// How often the agent checks whether the indicator should be up
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait before trying again after the window could not be shown
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Indicator settings, `[input_indicator]` in `client.toml`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndicatorPolicy {
+    /// Show the indicator during console and ad-hoc sessions
+    pub enabled: bool,
+    /// Show it during backstage sessions too
+    pub backstage: bool,
+}
+
+impl Default for IndicatorPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            backstage: false,
+        }
+    }
+}
+
+impl IndicatorPolicy {
+    /// Show the indicator for every session, as the server's policy may
+    /// require
+    pub fn require(&mut self) {
+        self.enabled = true;
+        self.backstage = true;
+    }
+}
+
+/// A running session, as far as the indicator cares
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlledSession {
+    pub session_type: SessionType,
+    pub technician: Option<String>,
+    /// Whether the technician's input reaches this machine now
+    pub injects_input: bool,
+}
+
+/// What the indicator should say for `sessions`, or `None` to hide it
+pub fn label(sessions: &[ControlledSession], policy: &IndicatorPolicy) -> Option<String> {
+    if !policy.enabled {
+        return None;
+    }
+    let mut names: Vec<&str> = Vec::new();
+    for session in sessions {
+        if !session.injects_input || (session.session_type == SessionType::Backstage && !policy.backstage) {
+            continue;
+        }
+        let name = session.technician.as_deref().unwrap_or("A technician");
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    (!names.is_empty()).then(|| format!("{} is controlling this computer", names.join(", ")))
+}
+
+/// The indicator's window on this platform
+pub trait IndicatorWindow: Send {
+    /// Show the window with `label`, or change the label of the one shown
+    fn show(&mut self, label: &str) -> Result<()>;
+
+    fn hide(&mut self);
+}
+
+/// Shows and hides an [`IndicatorWindow`] as the wanted label changes. A
+/// window that fails to show is tried again after [`RETRY_AFTER`] rather
+/// than on every poll.
+pub struct IndicatorLifecycle<W: IndicatorWindow> {
+    window: W,
+    shown: Option<String>,
+    retry_at: Option<Instant>,
+}
+
+impl<W: IndicatorWindow> IndicatorLifecycle<W> {
+    pub fn new(window: W) -> Self {
+        Self { window, shown: None, retry_at: None }
+    }
+
+    /// Bring the window in line with `wanted`
+    pub fn update(&mut self, wanted: Option<String>, now: Instant) {
+        if wanted == self.shown {
+            return;
+        }
+        let Some(label) = wanted else {
+            self.window.hide();
+            self.shown = None;
+            self.retry_at = None;
+            info!("Remote input indicator hidden");
+            return;
+        };
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+            return;
+        }
+        match self.window.show(&label) {
+            Ok(()) => {
+                info!("Remote input indicator: {}", label);
+                self.shown = Some(label);
+                self.retry_at = None;
+            }
+            Err(e) => {
+                warn!("Failed to show the remote input indicator: {}", e);
+                if self.shown.take().is_some() {
+                    self.window.hide();
+                }
+                self.retry_at = Some(now + RETRY_AFTER);
+            }
+        }
+    }
+
+    /// Label on screen now
+    pub fn shown(&self) -> Option<&str> {
+        self.shown.as_deref()
+    }
+}
+
+/// The window for this platform
+pub fn platform_window() -> Box<dyn IndicatorWindow> {
+    #[cfg(target_os = "linux")]
+    return Box::new(x11::X11Indicator::default());
+    #[cfg(windows)]
+    return Box::new(win32::Win32Indicator::default());
+    #[cfg(target_os = "macos")]
+    return Box::new(macos::MacIndicator::default());
+    #[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+    Box::new(Unsupported)
+}
+
+impl IndicatorWindow for Box<dyn IndicatorWindow> {
+    fn show(&mut self, label: &str) -> Result<()> {
+        self.as_mut().show(label)
+    }
+
+    fn hide(&mut self) {
+        self.as_mut().hide()
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+struct Unsupported;
+
+#[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+impl IndicatorWindow for Unsupported {
+    fn show(&mut self, _label: &str) -> Result<()> {
+        Err(crate::error::GhostLinkError::Other("No input indicator on this platform".to_string()))
+    }
+
+    fn hide(&mut self) {}
+}
+
+/// Width of the indicator for `label`, from an average glyph width
+#[cfg_attr(not(any(target_os = "linux", windows)), allow(dead_code))]
+fn window_width(label: &str, glyph_width: u16) -> u16 {
+    (label.chars().count() as u16).saturating_mul(glyph_width).saturating_add(24)
+}
+
+/// Override-redirect X11 window in the top-right corner with an empty input
+/// shape, so clicks go to whatever is below it
+#[cfg(target_os = "linux")]
+mod x11 {
+    use x11rb::connection::Connection;
+    use x11rb::protocol::shape::SK;
+    use x11rb::protocol::xfixes::ConnectionExt as _;
+    use x11rb::protocol::xproto::{
+        ConfigureWindowAux, ConnectionExt as _, CreateGCAux, CreateWindowAux, EventMask, StackMode, WindowClass,
+    };
+    use x11rb::rust_connection::RustConnection;
+
+    use super::{window_width, IndicatorWindow};
+    use crate::error::{GhostLinkError, Result};
+
+    const HEIGHT: u16 = 26;
+    const MARGIN: i16 = 12;
+    /// Glyph width of the core `fixed` font
+    const GLYPH_WIDTH: u16 = 7;
+    const BACKGROUND: u32 = 0xc6_28_28;
+    const FOREGROUND: u32 = 0xff_ff_ff;
+
+    #[derive(Default)]
+    pub struct X11Indicator {
+        window: Option<(RustConnection, u32)>,
+    }
+
+    fn x11_error(e: impl std::fmt::Display) -> GhostLinkError {
+        GhostLinkError::Other(format!("X11: {}", e))
+    }
+
+    impl X11Indicator {
+        fn open(label: &str) -> Result<(RustConnection, u32)> {
+            let (conn, screen_num) = x11rb::connect(None).map_err(x11_error)?;
+            let screen = &conn.setup().roots[screen_num];
+            let width = window_width(label, GLYPH_WIDTH);
+            let x = screen.width_in_pixels as i16 - width as i16 - MARGIN;
+
+            let window = conn.generate_id().map_err(x11_error)?;
+            conn.create_window(
+                x11rb::COPY_DEPTH_FROM_PARENT,
+                window,
+                screen.root,
+                x,
+                MARGIN,
+                width,
+                HEIGHT,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                0,
+                &CreateWindowAux::new()
+                    .background_pixel(BACKGROUND)
+                    .override_redirect(1)
+                    .event_mask(EventMask::EXPOSURE),
+            ).map_err(x11_error)?;
+
+            // An empty input region passes every click through
+            conn.xfixes_query_version(5, 0).map_err(x11_error)?;
+            let region = conn.generate_id().map_err(x11_error)?;
+            conn.xfixes_create_region(region, &[]).map_err(x11_error)?;
+            conn.xfixes_set_window_shape_region(window, SK::INPUT, 0, 0, region).map_err(x11_error)?;
+            conn.xfixes_destroy_region(region).map_err(x11_error)?;
+
+            conn.map_window(window).map_err(x11_error)?;
+            Ok((conn, window))
+        }
+
+        fn draw(conn: &RustConnection, window: u32, label: &str) -> Result<()> {
+            let font = conn.generate_id().map_err(x11_error)?;
+            conn.open_font(font, b"fixed").map_err(x11_error)?;
+            let gc = conn.generate_id().map_err(x11_error)?;
+            conn.create_gc(gc, window, &CreateGCAux::new().foreground(FOREGROUND).background(BACKGROUND).font(font))
+                .map_err(x11_error)?;
+
+            let width = window_width(label, GLYPH_WIDTH);
+            let screen_width = conn.setup().roots.first().map_or(width, |screen| screen.width_in_pixels);
+            conn.configure_window(window, &ConfigureWindowAux::new()
+                .x((screen_width as i16 - width as i16 - MARGIN) as i32)
+                .width(width as u32)
+                .stack_mode(StackMode::ABOVE))
+                .map_err(x11_error)?;
+            conn.clear_area(false, window, 0, 0, 0, 0).map_err(x11_error)?;
+            // Core text requests take Latin-1
+            let text: Vec<u8> = label.chars().map(|c| if (c as u32) < 256 { c as u8 } else { b'?' }).take(255).collect();
+            conn.image_text8(window, gc, 12, 17, &text).map_err(x11_error)?;
+            conn.free_gc(gc).map_err(x11_error)?;
+            conn.close_font(font).map_err(x11_error)?;
+            conn.flush().map_err(x11_error)?;
+            Ok(())
+        }
+    }
+
+    impl IndicatorWindow for X11Indicator {
+        fn show(&mut self, label: &str) -> Result<()> {
+            if self.window.is_none() {
+                self.window = Some(Self::open(label)?);
+            }
+            let (conn, window) = self.window.as_ref().expect("opened above");
+            if let Err(e) = Self::draw(conn, *window, label) {
+                self.hide();
+                return Err(e);
+            }
+            Ok(())
+        }
+
+        fn hide(&mut self) {
+            if let Some((conn, window)) = self.window.take() {
+                let _ = conn.destroy_window(window);
+                let _ = conn.flush();
+            }
+        }
+    }
+}
+
+/// Layered, topmost tool window that is transparent to the mouse, run on its
+/// own thread with its own message loop
+#[cfg(windows)]
+mod win32 {
+    use std::sync::mpsc;
+    use std::thread;
+    use windows::core::{w, PCWSTR};
+    use windows::Win32::Foundation::{COLORREF, HWND, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::Graphics::Gdi::{
+        BeginPaint, CreateSolidBrush, DeleteObject, DrawTextW, EndPaint, FillRect, InvalidateRect, SetBkMode,
+        SetTextColor, DT_CENTER, DT_SINGLELINE, DT_VCENTER, PAINTSTRUCT, TRANSPARENT,
+    };
+    use windows::Win32::UI::WindowsAndMessaging::{
+        CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetMessageW, GetSystemMetrics,
+        PostQuitMessage, PostThreadMessageW, RegisterClassW, SetLayeredWindowAttributes, SetWindowPos,
+        ShowWindow, TranslateMessage, HWND_TOPMOST, LWA_ALPHA, MSG, SM_CXSCREEN, SWP_NOACTIVATE,
+        SW_SHOWNOACTIVATE, WM_APP, WM_DESTROY, WM_PAINT, WM_QUIT, WNDCLASSW, WS_EX_LAYERED, WS_EX_NOACTIVATE,
+        WS_EX_TOOLWINDOW, WS_EX_TOPMOST, WS_EX_TRANSPARENT, WS_POPUP,
+    };
+
+    use super::{window_width, IndicatorWindow};
+    use crate::error::{GhostLinkError, Result};
+
+    const HEIGHT: i32 = 30;
+    const MARGIN: i32 = 12;
+    const GLYPH_WIDTH: u16 = 8;
+    /// Posted with the new label's length; the label itself is in `LABEL`
+    const WM_SET_LABEL: u32 = WM_APP + 1;
+
+    thread_local! {
+        static LABEL: std::cell::RefCell<Vec<u16>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    #[derive(Default)]
+    pub struct Win32Indicator {
+        thread: Option<(u32, mpsc::Sender<String>, thread::JoinHandle<()>)>,
+    }
+
+    extern "system" fn window_proc(hwnd: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        unsafe {
+            match message {
+                WM_PAINT => {
+                    let mut paint = PAINTSTRUCT::default();
+                    let dc = BeginPaint(hwnd, &mut paint);
+                    let brush = CreateSolidBrush(COLORREF(0x28_28_c6));
+                    FillRect(dc, &paint.rcPaint, brush);
+                    let _ = DeleteObject(brush);
+                    SetBkMode(dc, TRANSPARENT);
+                    SetTextColor(dc, COLORREF(0xff_ff_ff));
+                    let mut rect = paint.rcPaint;
+                    LABEL.with(|label| {
+                        let mut text = label.borrow().clone();
+                        DrawTextW(dc, &mut text, &mut rect, DT_CENTER | DT_VCENTER | DT_SINGLELINE);
+                    });
+                    let _ = EndPaint(hwnd, &paint);
+                    LRESULT(0)
+                }
+                WM_DESTROY => {
+                    PostQuitMessage(0);
+                    LRESULT(0)
+                }
+                _ => DefWindowProcW(hwnd, message, wparam, lparam),
+            }
+        }
+    }
+
+    fn place(hwnd: HWND, label: &str) {
+        let width = window_width(label, GLYPH_WIDTH) as i32;
+        unsafe {
+            let x = GetSystemMetrics(SM_CXSCREEN) - width - MARGIN;
+            let _ = SetWindowPos(hwnd, HWND_TOPMOST, x, MARGIN, width, HEIGHT, SWP_NOACTIVATE);
+            InvalidateRect(hwnd, None, true);
+        }
+    }
+
+    /// Create the window and pump its messages until told to quit
+    fn run(labels: mpsc::Receiver<String>, ready: mpsc::Sender<Result<u32>>) {
+        let class = w!("GhostLinkInputIndicator");
+        let hwnd = unsafe {
+            let wc = WNDCLASSW {
+                lpfnWndProc: Some(window_proc),
+                lpszClassName: class,
+                ..Default::default()
+            };
+            RegisterClassW(&wc);
+            CreateWindowExW(
+                WS_EX_LAYERED | WS_EX_TRANSPARENT | WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE,
+                class,
+                PCWSTR::null(),
+                WS_POPUP,
+                0,
+                0,
+                1,
+                1,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        if hwnd.0 == 0 {
+            let _ = ready.send(Err(GhostLinkError::Other(format!(
+                "CreateWindowExW failed: {}",
+                windows::core::Error::from_win32()
+            ))));
+            return;
+        }
+        unsafe {
+            let _ = SetLayeredWindowAttributes(hwnd, COLORREF(0), 220, LWA_ALPHA);
+            ShowWindow(hwnd, SW_SHOWNOACTIVATE);
+        }
+        let _ = ready.send(Ok(unsafe { windows::Win32::System::Threading::GetCurrentThreadId() }));
+
+        let mut message = MSG::default();
+        while unsafe { GetMessageW(&mut message, None, 0, 0) }.as_bool() {
+            if message.message == WM_SET_LABEL {
+                if let Ok(label) = labels.try_recv() {
+                    LABEL.with(|current| *current.borrow_mut() = label.encode_utf16().collect());
+                    place(hwnd, &label);
+                }
+                continue;
+            }
+            unsafe {
+                TranslateMessage(&message);
+                DispatchMessageW(&message);
+            }
+        }
+        unsafe {
+            let _ = DestroyWindow(hwnd);
+        }
+    }
+
+    impl IndicatorWindow for Win32Indicator {
+        fn show(&mut self, label: &str) -> Result<()> {
+            if self.thread.is_none() {
+                let (labels_tx, labels_rx) = mpsc::channel();
+                let (ready_tx, ready_rx) = mpsc::channel();
+                let handle = thread::Builder::new()
+                    .name("input-indicator".to_string())
+                    .spawn(move || run(labels_rx, ready_tx))?;
+                let thread_id = ready_rx.recv()
+                    .map_err(|_| GhostLinkError::Other("Input indicator thread exited".to_string()))??;
+                self.thread = Some((thread_id, labels_tx, handle));
+            }
+            let (thread_id, labels, _) = self.thread.as_ref().expect("started above");
+            labels.send(label.to_string())
+                .map_err(|_| GhostLinkError::Other("Input indicator thread exited".to_string()))?;
+            unsafe { PostThreadMessageW(*thread_id, WM_SET_LABEL, WPARAM(0), LPARAM(0)) }
+                .map_err(|e| GhostLinkError::Other(format!("PostThreadMessageW failed: {}", e)))?;
+            Ok(())
+        }
+
+        fn hide(&mut self) {
+            if let Some((thread_id, _, handle)) = self.thread.take() {
+                let _ = unsafe { PostThreadMessageW(thread_id, WM_QUIT, WPARAM(0), LPARAM(0)) };
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+/// A JavaScript for Automation process drawing a borderless panel that
+/// ignores the mouse; hiding kills it
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::process::{Child, Command, Stdio};
+
+    use super::IndicatorWindow;
+    use crate::error::Result;
+
+    const SCRIPT: &str = r#"
+ObjC.import('Cocoa');
+function run(argv) {
+    const label = argv[0];
+    const app = $.NSApplication.sharedApplication;
+    app.setActivationPolicy($.NSApplicationActivationPolicyAccessory);
+    const screen = $.NSScreen.mainScreen.frame;
+    const width = label.length * 7 + 24, height = 26;
+    const frame = $.NSMakeRect(screen.size.width - width - 12, screen.size.height - height - 36, width, height);
+    const panel = $.NSPanel.alloc.initWithContentRectStyleMaskBackingDefer(
+        frame, $.NSWindowStyleMaskBorderless | $.NSWindowStyleMaskNonactivatingPanel, $.NSBackingStoreBuffered, false);
+    panel.level = $.NSStatusWindowLevel;
+    panel.ignoresMouseEvents = true;
+    panel.collectionBehavior = $.NSWindowCollectionBehaviorCanJoinAllSpaces | $.NSWindowCollectionBehaviorStationary;
+    panel.backgroundColor = $.NSColor.colorWithSRGBRedGreenBlueAlpha(0.78, 0.16, 0.16, 0.9);
+    const text = $.NSTextField.labelWithString(label);
+    text.textColor = $.NSColor.whiteColor;
+    text.frame = $.NSMakeRect(12, 4, width - 24, height - 8);
+    panel.contentView.addSubview(text);
+    panel.orderFrontRegardless;
+    app.run;
+}
+"#;
+
+    #[derive(Default)]
+    pub struct MacIndicator {
+        process: Option<(String, Child)>,
+    }
+
+    impl IndicatorWindow for MacIndicator {
+        fn show(&mut self, label: &str) -> Result<()> {
+            if self.process.as_ref().is_some_and(|(shown, _)| shown == label) {
+                return Ok(());
+            }
+            // A new label is a new panel
+            self.hide();
+            let child = Command::new("osascript")
+                .args(["-l", "JavaScript", "-e", SCRIPT, label])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()?;
+            self.process = Some((label.to_string(), child));
+            Ok(())
+        }
+
+        fn hide(&mut self) {
+            if let Some((_, mut child)) = self.process.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GhostLinkError;
+    use std::sync::{Arc, Mutex};
+
+    /// Window that records what it was asked to do and fails on request
+    #[derive(Clone, Default)]
+    struct MockWindow {
+        calls: Arc<Mutex<Vec<String>>>,
+        failing: Arc<Mutex<bool>>,
+    }
+
+    impl IndicatorWindow for MockWindow {
+        fn show(&mut self, label: &str) -> Result<()> {
+            self.calls.lock().unwrap().push(format!("show {}", label));
+            if *self.failing.lock().unwrap() {
+                return Err(GhostLinkError::Other("no display".to_string()));
+            }
+            Ok(())
+        }
+
+        fn hide(&mut self) {
+            self.calls.lock().unwrap().push("hide".to_string());
+        }
+    }
+
+    fn session(session_type: SessionType, technician: &str, injects_input: bool) -> ControlledSession {
+        ControlledSession { session_type, technician: Some(technician.to_string()), injects_input }
+    }
+
+    #[test]
+    fn test_label_follows_sessions_and_policy() {
+        let policy = IndicatorPolicy::default();
+        assert_eq!(label(&[], &policy), None);
+
+        let console = session(SessionType::Console, "alice", true);
+        assert_eq!(label(&[console.clone()], &policy).unwrap(), "alice is controlling this computer");
+
+        // Blocked or previewed input, and silent backstage sessions, show nothing
+        let previewing = session(SessionType::Console, "bob", false);
+        let backstage = session(SessionType::Backstage, "carol", true);
+        assert_eq!(label(&[previewing.clone(), backstage.clone()], &policy), None);
+
+        let both = [console.clone(), session(SessionType::AdHoc, "dave", true), console.clone()];
+        assert_eq!(label(&both, &policy).unwrap(), "alice, dave is controlling this computer");
+
+        let mut required = IndicatorPolicy { enabled: false, backstage: false };
+        assert_eq!(label(&[console.clone()], &required), None);
+        required.require();
+        assert_eq!(label(&[backstage, previewing], &required).unwrap(), "carol is controlling this computer");
+    }
+
+    #[test]
+    fn test_lifecycle_shows_and_hides_once() {
+        let window = MockWindow::default();
+        let mut lifecycle = IndicatorLifecycle::new(window.clone());
+        let now = Instant::now();
+
+        lifecycle.update(None, now);
+        lifecycle.update(Some("alice".to_string()), now);
+        lifecycle.update(Some("alice".to_string()), now + POLL_INTERVAL);
+        assert_eq!(lifecycle.shown(), Some("alice"));
+        lifecycle.update(Some("alice, dave".to_string()), now + POLL_INTERVAL * 2);
+        // The session ends; the next poll takes the window down
+        lifecycle.update(None, now + POLL_INTERVAL * 3);
+        lifecycle.update(None, now + POLL_INTERVAL * 4);
+
+        assert_eq!(lifecycle.shown(), None);
+        assert_eq!(*window.calls.lock().unwrap(), ["show alice", "show alice, dave", "hide"]);
+    }
+
+    #[test]
+    fn test_lifecycle_backs_off_when_the_window_fails() {
+        let window = MockWindow::default();
+        *window.failing.lock().unwrap() = true;
+        let mut lifecycle = IndicatorLifecycle::new(window.clone());
+        let now = Instant::now();
+
+        lifecycle.update(Some("alice".to_string()), now);
+        lifecycle.update(Some("alice".to_string()), now + POLL_INTERVAL);
+        assert_eq!(lifecycle.shown(), None);
+        assert_eq!(window.calls.lock().unwrap().len(), 1);
+
+        *window.failing.lock().unwrap() = false;
+        lifecycle.update(Some("alice".to_string()), now + RETRY_AFTER);
+        assert_eq!(lifecycle.shown(), Some("alice"));
+
+        // Ending the session clears the back-off for the next one
+        lifecycle.update(None, now + RETRY_AFTER);
+        *window.failing.lock().unwrap() = true;
+        lifecycle.update(Some("bob".to_string()), now + RETRY_AFTER);
+        *window.failing.lock().unwrap() = false;
+        lifecycle.update(Some("bob".to_string()), now + RETRY_AFTER + POLL_INTERVAL);
+        assert_eq!(lifecycle.shown(), None);
+        assert_eq!(*window.calls.lock().unwrap(), ["show alice", "show alice", "hide", "show bob"]);
+    }
+}
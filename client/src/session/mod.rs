@@ -1,18 +1,42 @@
 #![allow(dead_code)]
 
+pub mod banner;
+pub mod commands;
+pub mod consent;
+pub mod event_sync;
+pub mod events;
+pub mod input_indicator;
+pub mod notes;
+pub mod preflight;
+pub mod shortcuts;
+pub mod token;
+pub mod watchdog;
 pub mod window;
+pub mod window_view;
 
-use anyhow::Result;
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
 use tracing::{error, info, warn};
 
-use crate::capture::ScreenCapture;
+use crate::capture::adaptive::CaptureStats;
+use crate::capture::cursor::CursorUpdate;
+use crate::capture::displays::{CaptureTarget, DisplayEvent};
+use crate::capture::encoder_profile::EncoderProfile;
+use crate::capture::negotiation::{StreamConfig, ViewerCapabilities};
+use crate::capture::{DisplayInfo, ScreenCapture};
 use crate::config::ClientConfig;
-use crate::input::InputController;
+use crate::connection::RelayConnection;
+use crate::elevation::{self, ElevatedContext, ElevationBackend, ElevationLevel, SessionElevation};
+use crate::error::{CaptureError, ElevationError, GhostLinkError, Result, SessionError};
+use crate::input::{InputController, InputEvent, InputMode, InputOutcome, InputStats};
+use crate::recording::{OperatorInfo, RecordingMetadata, SessionEventType, SessionRecorder};
 
+pub use preflight::SessionReadiness;
 pub use window::SessionWindow;
+use watchdog::{Component, HealthEvent, Supervised};
 
 // pub mod backstage;
 // pub mod console;
@@ -38,6 +62,56 @@ impl std::fmt::Display for SessionType {
     }
 }
 
+impl std::str::FromStr for SessionType {
+    type Err = GhostLinkError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "backstage" => Ok(SessionType::Backstage),
+            "console" => Ok(SessionType::Console),
+            "adhoc" => Ok(SessionType::AdHoc),
+            other => Err(SessionError::UnsupportedType { session_type: other.to_string() }.into()),
+        }
+    }
+}
+
+/// Limits on ad-hoc sessions, which end users start with a support code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdhocPolicy {
+    /// Seconds without input from the technician before an ad-hoc session
+    /// ends; 0 never ends it
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for AdhocPolicy {
+    fn default() -> Self {
+        Self { idle_timeout_secs: 15 * 60 }
+    }
+}
+
+/// Safety-net idle timeout for the sessions the relay normally times out
+/// itself; set longer than the server's so the technician gets its warning
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdlePolicy {
+    /// Seconds without technician activity before a session ends; 0 never
+    /// ends it
+    pub timeout_secs: u64,
+    /// Replaces `timeout_secs` for backstage sessions, which run long
+    /// without input
+    pub backstage_timeout_secs: u64,
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 60 * 60,
+            backstage_timeout_secs: 5 * 60 * 60,
+        }
+    }
+}
+
 /// Represents an active remote session
 #[derive(Clone)]
 pub struct Session {
@@ -46,119 +120,240 @@ pub struct Session {
     screen_capture: Arc<RwLock<Option<ScreenCapture>>>,
     input_controller: Arc<RwLock<Option<InputController>>>,
     is_active: Arc<RwLock<bool>>,
+    clipboard_sync: Arc<RwLock<bool>>,
+    recorder: Arc<RwLock<Option<Arc<SessionRecorder>>>>,
+    elevation: SessionElevation,
+    /// Last input from the technician, or the start of the session
+    last_activity: Arc<RwLock<Instant>>,
+    /// Idle time after which the session should end, if ever
+    idle_timeout: Option<Duration>,
+    /// Watchdog steps, for relaying to the viewer
+    health_events: broadcast::Sender<HealthEvent>,
+    /// Components the watchdog is restarting
+    recovering: Arc<parking_lot::Mutex<Vec<Component>>>,
+    /// Why the watchdog gave up on the session, once it has
+    failure: Arc<parking_lot::Mutex<Option<String>>>,
+    /// Events validated in preview mode, echoed to the viewer
+    input_previews: broadcast::Sender<InputEvent>,
+    /// Who requested the session, for the input indicator
+    technician: Arc<parking_lot::Mutex<Option<String>>>,
+    started_at: chrono::DateTime<chrono::Utc>,
     config: ClientConfig,
 }
 
+/// Opens the sessions the agent was asked for
+#[async_trait]
+pub trait SessionOpener: Send + Sync {
+    /// Open a session, or report the pre-flight that stopped it
+    async fn open(
+        &self,
+        id: String,
+        session_type: SessionType,
+        config: &ClientConfig,
+        relay: &RwLock<Option<RelayConnection>>,
+    ) -> std::result::Result<(Session, SessionReadiness), SessionReadiness>;
+}
+
+/// Sessions on this machine's own capture and input
+pub struct PlatformSessions;
+
+#[async_trait]
+impl SessionOpener for PlatformSessions {
+    async fn open(
+        &self,
+        id: String,
+        session_type: SessionType,
+        config: &ClientConfig,
+        relay: &RwLock<Option<RelayConnection>>,
+    ) -> std::result::Result<(Session, SessionReadiness), SessionReadiness> {
+        Session::new(id, session_type, config, relay).await
+    }
+}
+
 impl Session {
-    /// Create a new session
+    /// Create a new session once the pre-flight checks pass on its own
+    /// capture, input and `relay` connection. A session that fails one is
+    /// dropped with whatever was set up, and only the report comes back.
     pub async fn new(
         id: String,
         session_type: SessionType,
         config: &ClientConfig,
-    ) -> Result<Self> {
+        relay: &RwLock<Option<RelayConnection>>,
+    ) -> std::result::Result<(Self, SessionReadiness), SessionReadiness> {
         info!("Creating new {} session: {}", session_type, id);
         
-        let session = Self {
+        let mut probe = preflight::Probe::new(session_type, config, Some(relay));
+        let readiness = preflight::run_checks(&mut probe, &preflight::Check::ALL).await;
+        let (Some(capture), Some(input), true) = (probe.capture.take(), probe.input.take(), readiness.ready()) else {
+            if let Some(failure) = readiness.first_failure() {
+                warn!("Session {} failed its pre-flight: {}: {}", id, failure.check, failure.diagnostic);
+            }
+            return Err(readiness);
+        };
+        
+        let session = Self::detached(id, session_type, config);
+        *session.screen_capture.write().await = Some(capture);
+        *session.input_controller.write().await = Some(input);
+        
+        // Initialize session based on type
+        session.initialize_session().await;
+        session.start_watchdog();
+        
+        Ok((session, readiness))
+    }
+
+    /// Session with no capture or input attached yet
+    pub(crate) fn detached(id: String, session_type: SessionType, config: &ClientConfig) -> Self {
+        Self {
             id,
             session_type,
             screen_capture: Arc::new(RwLock::new(None)),
             input_controller: Arc::new(RwLock::new(None)),
             is_active: Arc::new(RwLock::new(false)),
+            clipboard_sync: Arc::new(RwLock::new(config.clipboard.enabled)),
+            recorder: Arc::new(RwLock::new(None)),
+            elevation: SessionElevation::new(),
+            last_activity: Arc::new(RwLock::new(Instant::now())),
+            idle_timeout: Some(match session_type {
+                SessionType::AdHoc => config.adhoc.idle_timeout_secs,
+                SessionType::Backstage => config.idle.backstage_timeout_secs,
+                SessionType::Console => config.idle.timeout_secs,
+            })
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+            health_events: broadcast::channel(16).0,
+            recovering: Arc::new(parking_lot::Mutex::new(Vec::new())),
+            failure: Arc::new(parking_lot::Mutex::new(None)),
+            input_previews: broadcast::channel(64).0,
+            technician: Arc::new(parking_lot::Mutex::new(None)),
+            started_at: chrono::Utc::now(),
             config: config.clone(),
-        };
-        
-        // Initialize session based on type
-        session.initialize_session().await?;
-        
-        Ok(session)
+        }
     }
 
-    /// Initialize session components based on session type
-    async fn initialize_session(&self) -> Result<()> {
+    /// Initialize session components based on session type; capture and
+    /// input are attached by the pre-flight
+    async fn initialize_session(&self) {
         match self.session_type {
-            SessionType::Backstage => {
-                self.initialize_backstage_session().await?;
-            }
-            SessionType::Console => {
-                self.initialize_console_session().await?;
-            }
-            SessionType::AdHoc => {
-                self.initialize_adhoc_session().await?;
-            }
+            SessionType::Backstage => self.initialize_backstage_session(),
+            SessionType::Console => self.initialize_console_session(),
+            SessionType::AdHoc => self.initialize_adhoc_session().await,
         }
         
         let mut active_guard = self.is_active.write().await;
         *active_guard = true;
         
         info!("Session {} initialized successfully", self.id);
+    }
+
+    /// Watch capture, encoder and input until the session stops, restarting
+    /// whichever fails; see `watchdog`
+    fn start_watchdog(&self) {
+        let session = self.clone();
+        tokio::spawn(async move {
+            let policy = session.config.watchdog.clone();
+            let outcome = watchdog::supervise(&session, &policy, |event| session.note_health(event)).await;
+            if let Some((component, reason)) = outcome {
+                error!("Session {} cannot recover its {}: {}", session.id, component, reason);
+                *session.failure.lock() = Some(reason);
+            }
+        });
+    }
+
+    fn note_health(&self, event: HealthEvent) {
+        {
+            let mut recovering = self.recovering.lock();
+            match &event {
+                HealthEvent::Recovering { component, .. } if !recovering.contains(component) => recovering.push(*component),
+                HealthEvent::Recovering { .. } => {}
+                HealthEvent::Recovered { component } | HealthEvent::Failed { component, .. } => {
+                    recovering.retain(|c| c != component)
+                }
+            }
+        }
+        let _ = self.health_events.send(event);
+    }
+
+    /// Watchdog steps of this session
+    pub fn subscribe_health_events(&self) -> broadcast::Receiver<HealthEvent> {
+        self.health_events.subscribe()
+    }
+
+    /// Components being restarted now, for the quality report
+    pub fn recovering(&self) -> Vec<Component> {
+        self.recovering.lock().clone()
+    }
+
+    /// Why the session has to end, if the watchdog could not recover it
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().clone()
+    }
+
+    /// Replace the input controller with a fresh one that keeps its scaling,
+    /// mode, preview and blocking
+    async fn restart_input(&self) -> Result<()> {
+        let mut fresh = InputController::new(self.session_type).await?;
+        let mut input_guard = self.input_controller.write().await;
+        if let Some(mut old) = input_guard.take() {
+            let inherited = fresh.inherit(&old).await;
+            if let Err(e) = old.cleanup().await {
+                warn!("Failed to clean up the replaced input controller: {}", e);
+            }
+            if let Err(e) = inherited {
+                warn!("Replacement input controller for session {} is not blocking input: {}", self.id, e);
+            }
+        }
+        *input_guard = Some(fresh);
+        info!("Input controller restarted for session {}", self.id);
         Ok(())
     }
 
     /// Initialize backstage (unattended admin) session
-    async fn initialize_backstage_session(&self) -> Result<()> {
+    fn initialize_backstage_session(&self) {
         info!("Initializing backstage session: {}", self.id);
         
         // For backstage sessions:
         // 1. No user notification
-        // 2. Start screen capture immediately
-        // 3. Enable full input control
-        // 4. Optional: blank user screen
-        // 5. Elevate privileges if needed
-        
-        // Initialize screen capture
-        let capture = ScreenCapture::new(self.session_type).await?;
-        let mut capture_guard = self.screen_capture.write().await;
-        *capture_guard = Some(capture);
-        
-        // Initialize input controller
-        let input = InputController::new(self.session_type).await?;
-        let mut input_guard = self.input_controller.write().await;
-        *input_guard = Some(input);
+        // 2. Screen capture and full input control, from the pre-flight
+        // 3. Optional: blank user screen
+        // 4. Elevate privileges once the server's PAM flow approves it
         
-        // TODO: Implement privilege elevation
+        // Elevation is not assumed up front: it arrives as an ElevationRequest
+        // after PAM approval, so every elevated session has an approval on record
         // TODO: Implement optional screen blanking
-        
-        Ok(())
     }
 
     /// Initialize console (interactive) session
-    async fn initialize_console_session(&self) -> Result<()> {
+    fn initialize_console_session(&self) {
         info!("Initializing console session: {}", self.id);
         
         // For console sessions:
         // 1. Show user notification (optional)
-        // 2. Start screen capture
-        // 3. Enable input control
-        // 4. User can see remote cursor
-        
-        // Initialize screen capture
-        let capture = ScreenCapture::new(self.session_type).await?;
-        let mut capture_guard = self.screen_capture.write().await;
-        *capture_guard = Some(capture);
-        
-        // Initialize input controller
-        let input = InputController::new(self.session_type).await?;
-        let mut input_guard = self.input_controller.write().await;
-        *input_guard = Some(input);
+        // 2. Screen capture and input control, from the pre-flight
+        // 3. User can see remote cursor
         
         // TODO: Show user notification
         // TODO: Enable remote cursor display
-        
-        Ok(())
     }
 
     /// Initialize ad-hoc (temporary) session
-    async fn initialize_adhoc_session(&self) -> Result<()> {
+    async fn initialize_adhoc_session(&self) {
         info!("Initializing ad-hoc session: {}", self.id);
         
         // Ad-hoc sessions are similar to console but temporary
         // They auto-expire and don't persist agent registration
         
-        self.initialize_console_session().await?;
-        
-        // TODO: Set session expiration timer
+        self.initialize_console_session();
         
-        Ok(())
+        // The agent polls `idle_expired` and ends the session once it is
+        self.touch().await;
+        if let Some(timeout) = self.idle_timeout {
+            info!("Ad-hoc session {} ends after {}s without input", self.id, timeout.as_secs());
+        }
+    }
+
+    fn capture_not_available(&self) -> GhostLinkError {
+        SessionError::CaptureNotAvailable { session_id: self.id.clone() }.into()
     }
 
     /// Start screen capture streaming
@@ -167,9 +362,11 @@ impl Session {
         
         if let Some(capture) = capture_guard.as_ref() {
             capture.start_streaming().await?;
+            // A viewer joining a running stream needs a complete picture first
+            capture.request_refresh();
             info!("Screen capture started for session: {}", self.id);
         } else {
-            return Err(anyhow::anyhow!("Screen capture not initialized"));
+            return Err(self.capture_not_available());
         }
         
         Ok(())
@@ -189,10 +386,18 @@ impl Session {
 
     /// Handle input event from remote operator
     pub async fn handle_input_event(&self, event_data: &serde_json::Value) -> Result<()> {
+        if !self.is_active().await {
+            warn!("Dropping input event for inactive session: {}", self.id);
+            return Ok(());
+        }
+        self.touch().await;
+        
         let input_guard = self.input_controller.read().await;
         
         if let Some(input) = input_guard.as_ref() {
-            input.handle_event(event_data).await?;
+            if let InputOutcome::Previewed(event) = input.handle_event(event_data).await? {
+                let _ = self.input_previews.send(event);
+            }
         } else {
             warn!("Input controller not initialized for session: {}", self.id);
         }
@@ -200,6 +405,332 @@ impl Session {
         Ok(())
     }
 
+    /// Add an input event to the recording timeline, if recording
+    pub async fn record_input(&self, event_type: &str, event_data: &serde_json::Value) {
+        if let Some(recorder) = self.recorder.read().await.as_ref() {
+            recorder.record_input(event_type, event_data);
+        }
+    }
+
+    /// Start recording this session to the configured recording directory
+    pub async fn start_recording(&self, operator: OperatorInfo) -> Result<Arc<SessionRecorder>> {
+        let mut recorder_guard = self.recorder.write().await;
+        if let Some(recorder) = recorder_guard.as_ref() {
+            return Ok(Arc::clone(recorder));
+        }
+
+        let recorder = Arc::new(SessionRecorder::start(&self.id, self.config.recording.clone(), operator)?);
+        recorder.record_session_event(SessionEventType::OperatorJoin, &format!("{} session started", self.session_type));
+        if let Some(capture) = self.screen_capture.read().await.as_ref() {
+            capture.set_recorder(Some(Arc::clone(&recorder)));
+        }
+        *recorder_guard = Some(Arc::clone(&recorder));
+        Ok(recorder)
+    }
+
+    /// Stop recording and finalize the recording's index
+    pub async fn stop_recording(&self) -> Result<Option<RecordingMetadata>> {
+        let Some(recorder) = self.recorder.write().await.take() else {
+            return Ok(None);
+        };
+        if let Some(capture) = self.screen_capture.read().await.as_ref() {
+            capture.set_recorder(None);
+        }
+        Ok(Some(recorder.stop().await?))
+    }
+
+    /// The session's recorder, while recording
+    pub async fn recorder(&self) -> Option<Arc<SessionRecorder>> {
+        self.recorder.read().await.clone()
+    }
+
+    /// Elevate the session's privileges and record the attempt on the
+    /// recording's timeline, whatever the outcome
+    pub async fn elevate(
+        &self,
+        backend: Arc<dyn ElevationBackend>,
+        level: ElevationLevel,
+    ) -> std::result::Result<ElevatedContext, ElevationError> {
+        let result = self.elevation.elevate(backend, level.clone()).await;
+        if let Some(recorder) = self.recorder.read().await.as_ref() {
+            recorder.record_session_event(SessionEventType::PermissionChange, &elevation::describe(&level, &result));
+        }
+        result
+    }
+
+    /// The session's elevation, for running commands with it
+    pub fn elevation(&self) -> &SessionElevation {
+        &self.elevation
+    }
+
+    /// Record the technician's viewer resolution so pointer input can be scaled
+    pub async fn set_viewer_resolution(&self, data: &serde_json::Value) -> Result<()> {
+        let width = data.get("width").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        let height = data.get("height").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        if width == 0 || height == 0 {
+            return Err(GhostLinkError::Protocol(format!("Invalid viewer resolution: {}x{}", width, height)));
+        }
+        
+        let screen = {
+            let capture_guard = self.screen_capture.read().await;
+            match capture_guard.as_ref() {
+                Some(capture) => {
+                    // Viewers report their resolution when they attach
+                    capture.request_refresh();
+                    capture.get_resolution().await
+                }
+                None => return Err(self.capture_not_available()),
+            }
+        };
+        
+        let input_guard = self.input_controller.read().await;
+        if let Some(input) = input_guard.as_ref() {
+            input.set_resolutions((width, height), screen).await;
+        }
+        
+        Ok(())
+    }
+
+    /// Switch the encoder profile of this session's screen stream
+    pub async fn set_encoder_profile(&self, profile: EncoderProfile) -> Result<()> {
+        let capture_guard = self.screen_capture.read().await;
+        match capture_guard.as_ref() {
+            Some(capture) => {
+                capture.set_encoder_profile(profile).await?;
+                info!("Session {} now encoding with {} profile", self.id, profile);
+                Ok(())
+            }
+            None => Err(self.capture_not_available()),
+        }
+    }
+
+    /// Displays the viewer can pick from, and what is captured now
+    pub async fn monitor_list(&self) -> Result<(Vec<DisplayInfo>, CaptureTarget)> {
+        let capture_guard = self.screen_capture.read().await;
+        match capture_guard.as_ref() {
+            Some(capture) => Ok((capture.get_displays().await?, capture.capture_target().await)),
+            None => Err(self.capture_not_available()),
+        }
+    }
+
+    /// Stream only `display_id`
+    pub async fn select_monitor(&self, display_id: u32) -> Result<()> {
+        let capture_guard = self.screen_capture.read().await;
+        match capture_guard.as_ref() {
+            Some(capture) => capture.set_display(display_id).await,
+            None => Err(self.capture_not_available()),
+        }
+    }
+
+    /// Stream every display as one virtual desktop, or go back to the primary
+    pub async fn capture_all_monitors(&self, enabled: bool) -> Result<()> {
+        let capture_guard = self.screen_capture.read().await;
+        let capture = capture_guard.as_ref()
+            .ok_or_else(|| self.capture_not_available())?;
+        if enabled {
+            return capture.capture_all_displays().await;
+        }
+
+        let displays = capture.get_displays().await?;
+        let primary = displays.iter().find(|display| display.is_primary).or(displays.first())
+            .ok_or_else(|| CaptureError::CaptureFailed { reason: "no displays available".to_string() })?;
+        capture.set_display(primary.id).await
+    }
+
+    /// Display switches and hot-plug changes of this session's capture
+    pub async fn subscribe_display_events(&self) -> Option<broadcast::Receiver<DisplayEvent>> {
+        let capture_guard = self.screen_capture.read().await;
+        capture_guard.as_ref().map(|capture| capture.subscribe_display_events())
+    }
+
+    /// Cursor updates of this session's capture
+    pub async fn subscribe_cursor_updates(&self) -> Option<broadcast::Receiver<CursorUpdate>> {
+        let capture_guard = self.screen_capture.read().await;
+        capture_guard.as_ref().map(|capture| capture.subscribe_cursor_updates())
+    }
+
+    /// Turn cursor updates on or off for this session
+    pub async fn set_cursor_streaming(&self, enabled: bool) {
+        let capture_guard = self.screen_capture.read().await;
+        if let Some(capture) = capture_guard.as_ref() {
+            capture.set_cursor_streaming(enabled);
+            info!(
+                "Cursor streaming {} for session {}",
+                if enabled { "enabled" } else { "disabled" },
+                self.id
+            );
+        }
+    }
+
+    /// Switch the session between absolute and relative pointer input,
+    /// returning the mode now applied
+    pub async fn set_input_mode(&self, mode: InputMode) -> InputMode {
+        let input_guard = self.input_controller.read().await;
+        match input_guard.as_ref() {
+            Some(input) => input.set_input_mode(mode).await,
+            None => InputMode::Absolute,
+        }
+    }
+
+    /// Turn input preview on or off, returning whether it is on now; a
+    /// session without input has nothing to preview
+    pub async fn set_input_preview(&self, enabled: bool) -> bool {
+        let input_guard = self.input_controller.read().await;
+        match input_guard.as_ref() {
+            Some(input) => {
+                input.set_preview(enabled);
+                enabled
+            }
+            None => false,
+        }
+    }
+
+    /// Events validated in preview mode, as they come
+    pub fn subscribe_input_previews(&self) -> broadcast::Receiver<InputEvent> {
+        self.input_previews.subscribe()
+    }
+
+    /// Input events handled so far, if the session takes input
+    pub async fn input_stats(&self) -> Option<InputStats> {
+        self.input_controller.read().await.as_ref().map(|input| input.stats())
+    }
+
+    /// Whether the technician's input reaches this machine now: the session
+    /// is active and its input is neither blocked nor previewed
+    pub async fn injects_input(&self) -> bool {
+        if !self.is_active().await {
+            return false;
+        }
+        match self.input_controller.read().await.as_ref() {
+            Some(input) => !input.is_preview() && !input.is_input_blocked().await,
+            None => false,
+        }
+    }
+
+    /// Record who requested the session
+    pub fn set_technician(&self, name: &str) {
+        *self.technician.lock() = Some(name.to_string());
+    }
+
+    pub fn technician(&self) -> Option<String> {
+        self.technician.lock().clone()
+    }
+
+    /// Apply the technician's quality setting (0-100) to the screen stream
+    pub async fn set_quality(&self, quality: u8) -> Result<()> {
+        let capture_guard = self.screen_capture.read().await;
+        match capture_guard.as_ref() {
+            Some(capture) => {
+                capture.set_quality(quality);
+                Ok(())
+            }
+            None => Err(self.capture_not_available()),
+        }
+    }
+
+    /// Cap the screen stream's frame rate; a session without one has
+    /// nothing to cap
+    pub async fn set_max_fps(&self, max_fps: u32) {
+        if let Some(capture) = self.screen_capture.read().await.as_ref() {
+            capture.set_max_fps(max_fps);
+        }
+    }
+
+    /// Cap the screen stream's bandwidth, or lift the cap with `None`
+    pub async fn set_bandwidth_cap(&self, kbps: Option<u32>) {
+        if let Some(capture) = self.screen_capture.read().await.as_ref() {
+            capture.set_bandwidth_cap(kbps);
+        }
+    }
+
+    /// Hold the screen stream's bandwidth cap under the policy's ceiling
+    pub async fn set_bandwidth_ceiling(&self, kbps: Option<u32>) {
+        if let Some(capture) = self.screen_capture.read().await.as_ref() {
+            capture.set_bandwidth_ceiling(kbps);
+        }
+    }
+
+    /// Make the next frame of the screen stream a keyframe, e.g. because a
+    /// viewer joined mid-stream or failed to decode
+    pub async fn request_keyframe(&self) -> Result<()> {
+        let capture_guard = self.screen_capture.read().await;
+        match capture_guard.as_ref() {
+            Some(capture) => {
+                capture.request_keyframe();
+                Ok(())
+            }
+            None => Err(self.capture_not_available()),
+        }
+    }
+
+    /// Pick the screen stream's encoder from what the viewer decodes
+    pub async fn negotiate_stream(&self, viewer: ViewerCapabilities) -> Result<StreamConfig> {
+        let capture_guard = self.screen_capture.read().await;
+        match capture_guard.as_ref() {
+            Some(capture) => Ok(capture.negotiate(viewer).await?),
+            None => Err(self.capture_not_available()),
+        }
+    }
+
+    /// Fall back to a simpler codec after the viewer failed to decode the
+    /// screen stream
+    pub async fn downgrade_stream(&self) -> Result<StreamConfig> {
+        let capture_guard = self.screen_capture.read().await;
+        match capture_guard.as_ref() {
+            Some(capture) => Ok(capture.downgrade().await?),
+            None => Err(self.capture_not_available()),
+        }
+    }
+
+    /// Codec and encoder of the screen stream, if the session streams one
+    pub async fn stream_config(&self) -> Option<StreamConfig> {
+        self.screen_capture.read().await.as_ref().and_then(|capture| capture.stream_config())
+    }
+
+    /// Capture pipeline stats, if the session streams the screen
+    pub async fn capture_stats(&self) -> Option<CaptureStats> {
+        self.screen_capture.read().await.as_ref().map(|capture| capture.stats())
+    }
+
+    /// Pass link measurements on to the screen stream's adaptive controller
+    pub async fn record_link_quality(&self, rtt_ms: f64, loss_percent: f64) {
+        if let Some(capture) = self.screen_capture.read().await.as_ref() {
+            capture.record_link_quality(rtt_ms, loss_percent);
+        }
+    }
+
+    /// Turn clipboard synchronization on or off for this session
+    pub async fn set_clipboard_sync(&self, enabled: bool) -> Result<()> {
+        if enabled && !self.config.clipboard.enabled {
+            return Err(SessionError::DisabledByPolicy {
+                session_id: self.id.clone(),
+                feature: "Clipboard synchronization".to_string(),
+            }
+            .into());
+        }
+
+        let mut sync_guard = self.clipboard_sync.write().await;
+        *sync_guard = enabled;
+        info!(
+            "Clipboard sync {} for session {}",
+            if enabled { "enabled" } else { "disabled" },
+            self.id
+        );
+        Ok(())
+    }
+
+    /// Check if clipboard updates from the technician are applied
+    pub async fn clipboard_sync_enabled(&self) -> bool {
+        *self.clipboard_sync.read().await && self.is_active().await
+    }
+
+    /// Check if local clipboard changes are sent to the technician. Only
+    /// console and backstage sessions share the local clipboard.
+    pub async fn shares_local_clipboard(&self) -> bool {
+        matches!(self.session_type, SessionType::Console | SessionType::Backstage)
+            && self.clipboard_sync_enabled().await
+    }
+
     /// Enable screen blanking (hide user's screen)
     pub async fn enable_screen_blanking(&self) -> Result<()> {
         if self.session_type != SessionType::Backstage {
@@ -243,11 +774,29 @@ impl Session {
         Ok(())
     }
 
+    /// Note technician activity (input, chat or a keepalive), restarting the
+    /// idle timeout
+    pub async fn touch(&self) {
+        *self.last_activity.write().await = Instant::now();
+    }
+
+    /// Whether the session has been idle longer than its idle timeout
+    pub async fn idle_expired(&self) -> bool {
+        match self.idle_timeout {
+            Some(timeout) => self.last_activity.read().await.elapsed() >= timeout,
+            None => false,
+        }
+    }
+
     /// Get session type
     pub fn session_type(&self) -> SessionType {
         self.session_type
     }
 
+    pub fn started_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.started_at
+    }
+
     /// Check if session is active
     pub async fn is_active(&self) -> bool {
         let active_guard = self.is_active.read().await;
@@ -288,6 +837,17 @@ impl Session {
             error!("Error stopping screen capture: {}", e);
         }
         
+        if let Some(context) = self.elevation.revoke().await {
+            info!("Dropped {} elevation for session {}", context.level, self.id);
+            if let Some(recorder) = self.recorder.read().await.as_ref() {
+                recorder.record_session_event(SessionEventType::PermissionChange, &format!("Elevation to {} ended", context.level));
+            }
+        }
+        
+        if let Err(e) = self.stop_recording().await {
+            error!("Error finalizing recording: {}", e);
+        }
+        
         // Disable input blocking if enabled
         if let Err(e) = self.disable_input_blocking().await {
             error!("Error disabling input blocking: {}", e);
@@ -309,3 +869,83 @@ impl Session {
         Ok(())
     }
 }
+
+#[async_trait::async_trait]
+impl Supervised for Session {
+    async fn is_running(&self) -> bool {
+        self.is_active().await
+    }
+
+    /// A component the session doesn't have has nothing to restart
+    async fn is_healthy(&self, component: Component) -> bool {
+        match component {
+            Component::Capture => match self.screen_capture.read().await.as_ref() {
+                Some(capture) => capture.is_healthy().await,
+                None => true,
+            },
+            Component::Encoder => match self.screen_capture.read().await.as_ref() {
+                Some(capture) => capture.encoder_healthy().await,
+                None => true,
+            },
+            Component::Input => self.input_controller.read().await.as_ref().is_none_or(|input| input.is_healthy()),
+        }
+    }
+
+    async fn restart(&self, component: Component) -> Result<()> {
+        let capture_guard = self.screen_capture.read().await;
+        let capture = || capture_guard.as_ref().ok_or_else(|| self.capture_not_available());
+        match component {
+            Component::Capture => capture()?.restart_capturer().await,
+            Component::Encoder => capture()?.restart_encoder().await,
+            Component::Input => {
+                drop(capture_guard);
+                self.restart_input().await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_adhoc_sessions_expire_when_idle() {
+        let mut config = ClientConfig::new("wss://test.example.com".to_string(), None).unwrap();
+        let console = Session::detached("console".to_string(), SessionType::Console, &config);
+        let adhoc = Session::detached("adhoc".to_string(), SessionType::AdHoc, &config);
+        config.adhoc.idle_timeout_secs = 0;
+        let unlimited = Session::detached("unlimited".to_string(), SessionType::AdHoc, &config);
+
+        let idle_since = Instant::now() - Duration::from_secs(16 * 60);
+        for session in [&console, &adhoc, &unlimited] {
+            *session.last_activity.write().await = idle_since;
+        }
+        assert!(adhoc.idle_expired().await);
+        assert!(!console.idle_expired().await);
+        assert!(!unlimited.idle_expired().await);
+
+        adhoc.touch().await;
+        assert!(!adhoc.idle_expired().await);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_by_session_type() {
+        let mut config = ClientConfig::new("wss://test.example.com".to_string(), None).unwrap();
+        let console = Session::detached("console".to_string(), SessionType::Console, &config);
+        let backstage = Session::detached("backstage".to_string(), SessionType::Backstage, &config);
+        config.idle.timeout_secs = 0;
+        let unlimited = Session::detached("unlimited".to_string(), SessionType::Console, &config);
+
+        let idle_since = Instant::now() - Duration::from_secs(61 * 60);
+        for session in [&console, &backstage, &unlimited] {
+            *session.last_activity.write().await = idle_since;
+        }
+        assert!(console.idle_expired().await);
+        assert!(!backstage.idle_expired().await);
+        assert!(!unlimited.idle_expired().await);
+
+        console.touch().await;
+        assert!(!console.idle_expired().await);
+    }
+}
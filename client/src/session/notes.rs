@@ -0,0 +1,187 @@
+//! Session notes on the server
+//!
+//! The Notes tab sends every note to `/api/sessions/:id/notes` under the ID
+//! the window gave it, so a note sent again after a failed upload is stored
+//! once. Templates come from the organization's `/api/note-templates`. When
+//! the device's policy wants a close-out note, ending the session answers
+//! 412 until the session has one.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::connection::proxy::http_client;
+use crate::error::{GhostLinkError, Result};
+
+use super::window::SessionNote;
+
+/// A template the organization offers for notes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteTemplate {
+    pub id: Uuid,
+    pub name: String,
+    pub body: String,
+}
+
+/// What asking the server to end the session came to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    Ended,
+    /// The policy wants a close-out note first
+    CloseoutRequired,
+}
+
+#[derive(Deserialize)]
+struct TemplateList {
+    templates: Vec<NoteTemplate>,
+}
+
+#[derive(Deserialize)]
+struct TicketLink {
+    ticket_ref: Option<String>,
+}
+
+fn session_url(server_url: &str, session_id: &str, path: &str) -> String {
+    format!("{}/api/sessions/{}{}", server_url.trim_end_matches('/'), session_id, path)
+}
+
+/// Send `request`, turning an error status into an error naming `what`
+async fn send(request: reqwest::RequestBuilder, what: &str) -> Result<reqwest::Response> {
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error: serde_json::Value = response.json().await.unwrap_or_default();
+        return Err(GhostLinkError::Other(format!("{} failed ({}): {}", what, status, error["error"].as_str().unwrap_or("unknown error"))));
+    }
+    Ok(response)
+}
+
+/// Store a note with the server
+pub async fn upload(server_url: &str, session_id: &str, auth_token: &str, note: &SessionNote) -> Result<()> {
+    let body = serde_json::json!({
+        "id": note.id,
+        "body": note.content,
+        "template_id": note.template_id,
+        "private": note.is_private,
+        "closeout": note.closeout,
+    });
+    let request = http_client()
+        .post(session_url(server_url, session_id, "/notes"))
+        .bearer_auth(auth_token)
+        .json(&body);
+    send(request, "Note upload").await?;
+    Ok(())
+}
+
+/// Replace the text of a note the server has
+pub async fn edit(server_url: &str, session_id: &str, auth_token: &str, note_id: Uuid, content: &str) -> Result<()> {
+    let request = http_client()
+        .put(session_url(server_url, session_id, &format!("/notes/{}", note_id)))
+        .bearer_auth(auth_token)
+        .json(&serde_json::json!({ "body": content }));
+    send(request, "Note edit").await?;
+    Ok(())
+}
+
+/// Templates of the technician's organization, by name
+pub async fn templates(server_url: &str, auth_token: &str) -> Result<Vec<NoteTemplate>> {
+    let request = http_client()
+        .get(format!("{}/api/note-templates", server_url.trim_end_matches('/')))
+        .bearer_auth(auth_token);
+    let list: TemplateList = send(request, "Loading note templates").await?.json().await?;
+    Ok(list.templates)
+}
+
+/// Link the session to a PSA ticket, or unlink it with `None`; returns the
+/// reference as the server stored it
+pub async fn set_ticket(server_url: &str, session_id: &str, auth_token: &str, ticket_ref: Option<&str>) -> Result<Option<String>> {
+    let request = http_client()
+        .put(session_url(server_url, session_id, "/ticket"))
+        .bearer_auth(auth_token)
+        .json(&serde_json::json!({ "ticket_ref": ticket_ref }));
+    let link: TicketLink = send(request, "Linking the ticket").await?.json().await?;
+    Ok(link.ticket_ref)
+}
+
+/// Ask the server to end the session
+pub async fn end(server_url: &str, session_id: &str, auth_token: &str) -> Result<SessionEnd> {
+    let request = http_client()
+        .delete(session_url(server_url, session_id, ""))
+        .bearer_auth(auth_token);
+    let response = request.send().await?;
+    if response.status() == reqwest::StatusCode::PRECONDITION_FAILED {
+        return Ok(SessionEnd::CloseoutRequired);
+    }
+    if !response.status().is_success() {
+        let status = response.status();
+        let error: serde_json::Value = response.json().await.unwrap_or_default();
+        return Err(GhostLinkError::Other(format!("Ending the session failed ({}): {}", status, error["error"].as_str().unwrap_or("unknown error"))));
+    }
+    Ok(SessionEnd::Ended)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_notes_go_up_under_the_windows_id() {
+        let server = MockServer::start().await;
+        let note = SessionNote {
+            id: Uuid::new_v4(),
+            content: "Printing works again".to_string(),
+            author: "tech@example.com".to_string(),
+            timestamp: chrono::Utc::now(),
+            is_private: false,
+            template_id: None,
+            closeout: true,
+            edited_at: None,
+        };
+        Mock::given(method("POST"))
+            .and(path("/api/sessions/session-1/notes"))
+            .and(header("authorization", "Bearer token"))
+            .and(body_partial_json(serde_json::json!({ "id": note.id, "body": "Printing works again", "closeout": true })))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&server)
+            .await;
+        upload(&format!("{}/", server.uri()), "session-1", "token", &note).await.unwrap();
+
+        Mock::given(method("PUT"))
+            .and(path("/api/sessions/session-1/notes/00000000-0000-0000-0000-000000000000"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(serde_json::json!({ "error": "Only the author of a note or an admin may edit it" })))
+            .mount(&server)
+            .await;
+        let error = edit(&server.uri(), "session-1", "token", Uuid::nil(), "changed").await.unwrap_err();
+        assert!(error.to_string().contains("Only the author"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_ending_may_wait_for_a_closeout_note() {
+        let server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/sessions/held"))
+            .respond_with(ResponseTemplate::new(412).set_body_json(serde_json::json!({ "closeout_required": true })))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/sessions/done"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "status": "success" })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/note-templates"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "templates": [
+                { "id": Uuid::nil(), "organization_id": null, "name": "Printer fix", "body": "## Cause\n", "created_at": "2026-01-01T00:00:00Z" }
+            ] })))
+            .mount(&server)
+            .await;
+
+        assert_eq!(end(&server.uri(), "held", "token").await.unwrap(), SessionEnd::CloseoutRequired);
+        assert_eq!(end(&server.uri(), "done", "token").await.unwrap(), SessionEnd::Ended);
+        assert!(end(&server.uri(), "missing", "token").await.is_err());
+        let templates = templates(&server.uri(), "token").await.unwrap();
+        assert_eq!(templates, vec![NoteTemplate { id: Uuid::nil(), name: "Printer fix".to_string(), body: "## Cause\n".to_string() }]);
+    }
+}
@@ -0,0 +1,397 @@
+//! Session pre-flight checks
+//!
+//! Before a session reports itself active the agent proves each stage of it
+//! works: the capturer initializes, a frame can be grabbed, an encoder
+//! initializes and encodes that frame, input injection is available and the
+//! relay answers a ping. The outcome is a [`SessionReadiness`] report; a
+//! session that fails a check is aborted with the report attached to its
+//! `SessionResponse`, so the technician sees the cause instead of "session
+//! failed". `ghostlink-client doctor` runs the same checks outside a session.
+//! On macOS, capture and input first need their privacy permission; a
+//! missing one fails with [`FailureCode::PermissionMissing`] so the viewer
+//! can say where to grant it.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::capture::{Frame, ScreenCapture};
+use crate::config::ClientConfig;
+use crate::connection::{self, RelayConnection};
+use crate::error::{CaptureError, ConnectionError, GhostLinkError, PreflightError};
+use crate::input::InputController;
+use crate::permissions::{self, Permission};
+use super::SessionType;
+
+/// How long a frame grab, encoder or input check may take; capture has no
+/// limit since it may be waiting on the user's permission dialog
+const CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long the relay has to answer its ping
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One stage a session needs, in the order they are checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Capture,
+    FrameGrab,
+    Encoder,
+    Input,
+    Relay,
+}
+
+impl Check {
+    /// Everything a session is checked for
+    pub const ALL: [Check; 5] = [Check::Capture, Check::FrameGrab, Check::Encoder, Check::Input, Check::Relay];
+
+    /// The check that must pass for this one to be worth running
+    fn prerequisite(self) -> Option<Check> {
+        match self {
+            Check::FrameGrab => Some(Check::Capture),
+            Check::Encoder => Some(Check::FrameGrab),
+            Check::Capture | Check::Input | Check::Relay => None,
+        }
+    }
+
+    fn timeout(self) -> Option<Duration> {
+        match self {
+            Check::Capture => None,
+            Check::Relay => Some(RELAY_TIMEOUT),
+            Check::FrameGrab | Check::Encoder | Check::Input => Some(CHECK_TIMEOUT),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Check::Capture => "Capture initialize",
+            Check::FrameGrab => "Test frame grab",
+            Check::Encoder => "Encoder",
+            Check::Input => "Input handler",
+            Check::Relay => "Relay round-trip",
+        })
+    }
+}
+
+/// A failure the viewer walks the technician through fixing, sent along
+/// with the session's failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCode {
+    /// The end user has to grant the agent a macOS privacy permission
+    PermissionMissing,
+}
+
+/// Outcome of one check; the diagnostic says what was found, or why it failed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub check: Check,
+    pub passed: bool,
+    pub diagnostic: String,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<FailureCode>,
+}
+
+/// Every check of a pre-flight with its outcome
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionReadiness {
+    pub checks: Vec<CheckResult>,
+}
+
+impl SessionReadiness {
+    /// Whether every check passed
+    pub fn ready(&self) -> bool {
+        self.checks.iter().all(|result| result.passed)
+    }
+
+    /// The failure to report, which the checks skipped after it stem from
+    pub fn first_failure(&self) -> Option<&CheckResult> {
+        self.checks.iter().find(|result| !result.passed)
+    }
+
+    fn failed(&self, check: Check) -> bool {
+        self.checks.iter().any(|result| result.check == check && !result.passed)
+    }
+
+    /// The report as a plain-text table, for `doctor`
+    pub fn table(&self) -> String {
+        let width = self.checks.iter().map(|result| result.check.to_string().len()).max().unwrap_or(0);
+        let mut table = String::new();
+        for result in &self.checks {
+            table.push_str(&format!(
+                "{:<width$}  {}  {:>6} ms  {}\n",
+                result.check.to_string(),
+                if result.passed { "PASS" } else { "FAIL" },
+                result.duration_ms,
+                result.diagnostic,
+                width = width,
+            ));
+        }
+        table
+    }
+}
+
+/// Runs the individual checks; sessions run them on their own capture,
+/// input and relay connection, `doctor` on throwaway ones
+#[async_trait]
+pub trait ReadinessProbe: Send {
+    /// Run `check`, describing what was found
+    async fn run(&mut self, check: Check) -> Result<String, PreflightError>;
+}
+
+/// Run `checks` in order. Every check runs so the report is complete,
+/// except those whose prerequisite failed, which are reported as skipped.
+pub async fn run_checks<P: ReadinessProbe + ?Sized>(probe: &mut P, checks: &[Check]) -> SessionReadiness {
+    let mut readiness = SessionReadiness::default();
+    for &check in checks {
+        let started = Instant::now();
+        let outcome = match check.prerequisite().filter(|&prerequisite| readiness.failed(prerequisite)) {
+            Some(prerequisite) => Err(PreflightError::Skipped { check: prerequisite.to_string() }),
+            None => match check.timeout() {
+                Some(limit) => tokio::time::timeout(limit, probe.run(check)).await.unwrap_or_else(|_| {
+                    Err(PreflightError::TimedOut { check: check.to_string(), seconds: limit.as_secs() })
+                }),
+                None => probe.run(check).await,
+            },
+        };
+        let (passed, diagnostic, code) = match outcome {
+            Ok(found) => (true, found, None),
+            Err(e) => (false, e.to_string(), e.code()),
+        };
+        readiness.checks.push(CheckResult {
+            check,
+            passed,
+            diagnostic,
+            duration_ms: started.elapsed().as_millis() as u64,
+            code,
+        });
+    }
+    readiness
+}
+
+/// Why capture did not start, keeping a refused permission apart
+fn capture_failure(error: GhostLinkError) -> PreflightError {
+    match error {
+        GhostLinkError::Capture(CaptureError::PermissionDenied { backend }) => {
+            PreflightError::CapturePermissionDenied { backend }
+        }
+        GhostLinkError::Capture(e) => PreflightError::CaptureUnavailable { reason: e.to_string() },
+        e => PreflightError::CaptureUnavailable { reason: e.to_string() },
+    }
+}
+
+/// Probe for the checks, holding what each step set up for the next
+pub struct Probe<'a> {
+    session_type: SessionType,
+    config: &'a ClientConfig,
+    /// The agent's connection, for sessions; `doctor` opens its own. Only
+    /// locked for the ping, as capture may wait minutes on the user.
+    relay: Option<&'a RwLock<Option<RelayConnection>>>,
+    pub capture: Option<ScreenCapture>,
+    pub input: Option<InputController>,
+    frame: Option<Frame>,
+}
+
+impl<'a> Probe<'a> {
+    pub fn new(session_type: SessionType, config: &'a ClientConfig, relay: Option<&'a RwLock<Option<RelayConnection>>>) -> Self {
+        Self { session_type, config, relay, capture: None, input: None, frame: None }
+    }
+}
+
+#[async_trait]
+impl ReadinessProbe for Probe<'_> {
+    async fn run(&mut self, check: Check) -> Result<String, PreflightError> {
+        let capture = self.capture.as_ref();
+        match check {
+            Check::Capture => {
+                // Capture "works" without the permission, showing only black
+                permissions::require(Permission::ScreenRecording)?;
+                let capture = ScreenCapture::open(self.session_type, &self.config.encoding).await
+                    .map_err(capture_failure)?;
+                let (width, height) = capture.get_resolution().await;
+                self.capture = Some(capture);
+                Ok(format!("{}x{}", width, height))
+            }
+            Check::FrameGrab => {
+                let capture = capture.ok_or_else(|| PreflightError::Skipped { check: Check::Capture.to_string() })?;
+                let frame = capture.test_frame().await
+                    .map_err(|e| PreflightError::FrameGrabFailed { reason: e.to_string() })?;
+                let found = format!("{}x{} {:?}", frame.width, frame.height, frame.pixel_format);
+                self.frame = Some(frame);
+                Ok(found)
+            }
+            Check::Encoder => {
+                let capture = capture.ok_or_else(|| PreflightError::Skipped { check: Check::Capture.to_string() })?;
+                capture.start_encoder(self.frame.as_ref()).await
+                    .map_err(|e| PreflightError::EncoderUnavailable { reason: e.to_string() })?;
+                match capture.get_encoder_info().await {
+                    Some(info) => Ok(format!("{} ({})", info.name, if info.hardware_accelerated { "hardware" } else { "software" })),
+                    None => Ok("ready".to_string()),
+                }
+            }
+            Check::Input => {
+                permissions::require(Permission::Accessibility)?;
+                let input = InputController::new(self.session_type).await
+                    .map_err(|e| PreflightError::InputUnavailable { reason: format!("{:#}", e) })?;
+                // Walk an event through the input path in preview, so the
+                // user's pointer stays where it is
+                input.set_preview(true);
+                let outcome = input.handle_event(&serde_json::json!({"type": "MouseScroll", "delta_x": 0, "delta_y": 0})).await;
+                input.set_preview(false);
+                outcome.map_err(|e| PreflightError::InputUnavailable { reason: format!("{:#}", e) })?;
+                self.input = Some(input);
+                Ok("ready".to_string())
+            }
+            Check::Relay => {
+                let rtt = match self.relay {
+                    Some(relay) => match relay.read().await.as_ref() {
+                        Some(connection) => connection.round_trip().await,
+                        None => Err(ConnectionError::NotConnected.into()),
+                    },
+                    None => connection::probe_relay(self.config).await,
+                }
+                .map_err(|e| PreflightError::RelayUnreachable { reason: format!("{:#}", e) })?;
+                Ok(format!("{} ms", rtt.as_millis()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Answers each check as scripted; unscripted checks pass
+    struct ScriptedProbe {
+        failures: HashMap<Check, PreflightError>,
+        ran: Vec<Check>,
+        hang: Option<Check>,
+    }
+
+    impl ScriptedProbe {
+        fn failing(failures: Vec<(Check, PreflightError)>) -> Self {
+            Self { failures: failures.into_iter().collect(), ran: Vec::new(), hang: None }
+        }
+    }
+
+    #[async_trait]
+    impl ReadinessProbe for ScriptedProbe {
+        async fn run(&mut self, check: Check) -> Result<String, PreflightError> {
+            self.ran.push(check);
+            if self.hang == Some(check) {
+                std::future::pending::<()>().await;
+            }
+            match self.failures.get(&check) {
+                Some(e) => Err(e.clone()),
+                None => Ok("ok".to_string()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_all_checks_pass() {
+        let mut probe = ScriptedProbe::failing(Vec::new());
+        let readiness = run_checks(&mut probe, &Check::ALL).await;
+
+        assert!(readiness.ready());
+        assert!(readiness.first_failure().is_none());
+        assert_eq!(probe.ran, Check::ALL);
+        assert_eq!(readiness.checks.iter().map(|result| result.check).collect::<Vec<_>>(), Check::ALL);
+    }
+
+    #[tokio::test]
+    async fn test_denied_capture_skips_what_depends_on_it() {
+        let denied = PreflightError::CapturePermissionDenied { backend: "Portal".to_string() };
+        let mut probe = ScriptedProbe::failing(vec![
+            (Check::Capture, denied),
+            (Check::Relay, PreflightError::RelayUnreachable { reason: "connection refused".to_string() }),
+        ]);
+        let readiness = run_checks(&mut probe, &Check::ALL).await;
+
+        assert!(!readiness.ready());
+        // Frame grab and encoder never ran, input still did
+        assert_eq!(probe.ran, vec![Check::Capture, Check::Input, Check::Relay]);
+        let outcomes: Vec<_> = readiness.checks.iter().map(|result| (result.check, result.passed)).collect();
+        assert_eq!(outcomes, vec![
+            (Check::Capture, false),
+            (Check::FrameGrab, false),
+            (Check::Encoder, false),
+            (Check::Input, true),
+            (Check::Relay, false),
+        ]);
+
+        let failure = readiness.first_failure().unwrap();
+        assert_eq!(failure.check, Check::Capture);
+        assert_eq!(failure.diagnostic, "Screen capture permission denied by Portal");
+        assert_eq!(readiness.checks[1].diagnostic, "Not run, Capture initialize failed");
+        assert_eq!(readiness.checks[4].diagnostic, "Relay unreachable: connection refused");
+    }
+
+    #[tokio::test]
+    async fn test_failed_encoder_is_the_reported_cause() {
+        let mut probe = ScriptedProbe::failing(vec![
+            (Check::Encoder, PreflightError::EncoderUnavailable { reason: "no H.264 encoder".to_string() }),
+        ]);
+        let readiness = run_checks(&mut probe, &Check::ALL).await;
+
+        assert_eq!(probe.ran, Check::ALL);
+        assert_eq!(readiness.checks.iter().filter(|result| !result.passed).count(), 1);
+        assert_eq!(readiness.first_failure().unwrap().check, Check::Encoder);
+
+        let table = readiness.table();
+        assert_eq!(table.lines().count(), Check::ALL.len());
+        assert!(table.lines().nth(2).unwrap().contains("FAIL"));
+        assert!(table.contains("No usable video encoder: no H.264 encoder"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_permission_carries_its_code() {
+        let missing = PreflightError::PermissionMissing {
+            permission: "Screen Recording".to_string(),
+            remediation: Permission::ScreenRecording.remediation(),
+        };
+        let mut probe = ScriptedProbe::failing(vec![(Check::Capture, missing)]);
+        let readiness = run_checks(&mut probe, &Check::ALL).await;
+
+        let failure = readiness.first_failure().unwrap();
+        assert_eq!(failure.code, Some(FailureCode::PermissionMissing));
+        assert!(failure.diagnostic.starts_with("Screen Recording permission missing: grant Screen Recording"));
+        // Checks skipped because of it have nothing to fix themselves
+        assert_eq!(readiness.checks[1].code, None);
+        let json = serde_json::to_value(&readiness).unwrap();
+        assert_eq!(json["checks"][0]["code"], "permission_missing");
+        assert!(json["checks"][4].get("code").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hung_check_times_out() {
+        let mut probe = ScriptedProbe::failing(Vec::new());
+        probe.hang = Some(Check::Relay);
+        let readiness = run_checks(&mut probe, &[Check::Input, Check::Relay]).await;
+
+        assert!(readiness.checks[0].passed);
+        assert_eq!(readiness.first_failure().unwrap().diagnostic, "Relay round-trip did not finish within 5s");
+    }
+
+    #[test]
+    fn test_report_round_trips_as_json() {
+        let readiness = SessionReadiness {
+            checks: vec![CheckResult {
+                check: Check::FrameGrab,
+                passed: false,
+                diagnostic: "No frame captured: timeout".to_string(),
+                duration_ms: 12,
+                code: None,
+            }],
+        };
+        let json = serde_json::to_value(&readiness).unwrap();
+        assert_eq!(json["checks"][0]["check"], "frame_grab");
+        assert_eq!(serde_json::from_value::<SessionReadiness>(json).unwrap(), readiness);
+    }
+}
@@ -0,0 +1,155 @@
+//! Confirmation for destructive combos
+//!
+//! With two session windows open, Ctrl+Alt+Del or Alt+F4 sent to the wrong
+//! machine is easy to do and hard to take back. Each window registers a
+//! marker file named after its process, so every viewer process can count
+//! the windows open on this machine; markers of processes that are gone are
+//! cleared when counted.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use sysinfo::{Pid, System};
+use tracing::warn;
+
+use super::ShortcutAction;
+use crate::error::Result;
+
+/// Where session windows register
+pub fn default_dir() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("ghostlink")
+        .join("session-windows")
+}
+
+/// This window's registration; dropping it unregisters
+pub struct OpenWindows {
+    dir: PathBuf,
+    marker: PathBuf,
+}
+
+impl OpenWindows {
+    pub fn register(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let marker = dir.join(format!("{}-{}", std::process::id(), uuid::Uuid::new_v4()));
+        fs::write(&marker, b"")?;
+        Ok(Self { dir: dir.to_path_buf(), marker })
+    }
+
+    /// Session windows open now, this one included
+    pub fn count(&self) -> usize {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return 1;
+        };
+        let mut system = System::new();
+        let mut open = 0;
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(pid) = name.to_str().and_then(|name| name.split('-').next()).and_then(|pid| pid.parse::<u32>().ok()) else {
+                continue;
+            };
+            if pid == std::process::id() || system.refresh_process(Pid::from_u32(pid)) {
+                open += 1;
+            } else {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        open.max(1)
+    }
+}
+
+impl Drop for OpenWindows {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.marker);
+    }
+}
+
+enum Windows {
+    Registered(OpenWindows),
+    /// Registration failed, so there may be others
+    Unknown,
+    #[cfg(test)]
+    Fixed(usize),
+}
+
+/// Decides which combos wait for confirmation
+pub struct HotkeyGuard {
+    windows: Windows,
+}
+
+impl HotkeyGuard {
+    /// Register this window in [`default_dir`]. A window that can't register
+    /// asks every time.
+    pub fn register() -> Self {
+        match OpenWindows::register(&default_dir()) {
+            Ok(windows) => Self { windows: Windows::Registered(windows) },
+            Err(e) => {
+                warn!("Failed to register the session window: {}", e);
+                Self { windows: Windows::Unknown }
+            }
+        }
+    }
+
+    pub fn needs_confirmation(&self, action: ShortcutAction) -> bool {
+        action.is_destructive() && match &self.windows {
+            Windows::Registered(windows) => windows.count() > 1,
+            Windows::Unknown => true,
+            #[cfg(test)]
+            Windows::Fixed(count) => *count > 1,
+        }
+    }
+
+    /// A guard for the only window open
+    #[cfg(test)]
+    pub fn unguarded() -> Self {
+        Self { windows: Windows::Fixed(1) }
+    }
+
+    /// A guard with another window open
+    #[cfg(test)]
+    pub fn confirming() -> Self {
+        Self { windows: Windows::Fixed(2) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_are_counted_and_stale_markers_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = OpenWindows::register(dir.path()).unwrap();
+        assert_eq!(first.count(), 1);
+
+        let second = OpenWindows::register(dir.path()).unwrap();
+        // A viewer that crashed, and a file that isn't a marker
+        let stale = dir.path().join("999999999-gone");
+        fs::write(&stale, b"").unwrap();
+        fs::write(dir.path().join("notes"), b"").unwrap();
+        assert_eq!(first.count(), 2);
+        assert!(!stale.exists());
+
+        drop(second);
+        assert_eq!(first.count(), 1);
+        drop(first);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_only_destructive_combos_need_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = HotkeyGuard { windows: Windows::Registered(OpenWindows::register(dir.path()).unwrap()) };
+        assert!(!guard.needs_confirmation(ShortcutAction::SecureAttention));
+
+        let _other = OpenWindows::register(dir.path()).unwrap();
+        assert!(guard.needs_confirmation(ShortcutAction::SecureAttention));
+        assert!(guard.needs_confirmation(ShortcutAction::CloseApp));
+        assert!(!guard.needs_confirmation(ShortcutAction::SwitchApp));
+        assert!(!guard.needs_confirmation(ShortcutAction::Screenshot));
+
+        let unknown = HotkeyGuard { windows: Windows::Unknown };
+        assert!(unknown.needs_confirmation(ShortcutAction::LockScreen));
+        assert!(!unknown.needs_confirmation(ShortcutAction::VolumeMute));
+    }
+}
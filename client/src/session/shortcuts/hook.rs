@@ -0,0 +1,572 @@
+//! Low-level keyboard hooks
+//!
+//! While "Send system shortcuts" is on and the session window has focus, a
+//! hook takes the reserved combos before the technician's operating system
+//! acts on them: a `WH_KEYBOARD_LL` hook on Windows, a `CGEventTap` on macOS
+//! and a keyboard grab on X11. On Windows and macOS the hook takes only
+//! what [`HookFilter`] picks and every other key reaches the window as
+//! usual. An X11 grab takes the whole keyboard, so that hook reports every
+//! key. Wayland lets no application do this.
+
+use crate::error::Result;
+use crate::input::KeyCode;
+
+use super::{is_modifier, tables, Platform};
+
+/// A key the hook took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyInput {
+    pub key: KeyCode,
+    pub pressed: bool,
+}
+
+/// The keyboard hook on this platform
+pub trait KeyboardHook: Send {
+    /// Start or stop taking keys
+    fn set_active(&mut self, active: bool) -> Result<()>;
+
+    /// Next key taken since the last call, without waiting
+    fn next_key(&mut self) -> Option<KeyInput>;
+
+    /// Whether the active hook takes every key, so the window sees none
+    fn exclusive(&self) -> bool {
+        false
+    }
+}
+
+impl KeyboardHook for Box<dyn KeyboardHook> {
+    fn set_active(&mut self, active: bool) -> Result<()> {
+        self.as_mut().set_active(active)
+    }
+
+    fn next_key(&mut self) -> Option<KeyInput> {
+        self.as_mut().next_key()
+    }
+
+    fn exclusive(&self) -> bool {
+        self.as_ref().exclusive()
+    }
+}
+
+/// The hook for this platform
+pub fn platform_hook() -> Box<dyn KeyboardHook> {
+    #[cfg(target_os = "linux")]
+    return Box::new(x11::X11Hook::default());
+    #[cfg(windows)]
+    return Box::new(win32::Win32Hook::default());
+    #[cfg(target_os = "macos")]
+    return Box::new(macos::MacHook::default());
+    #[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+    Box::new(Unsupported)
+}
+
+#[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+struct Unsupported;
+
+#[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+impl KeyboardHook for Unsupported {
+    fn set_active(&mut self, active: bool) -> Result<()> {
+        if !active {
+            return Ok(());
+        }
+        Err(crate::error::GhostLinkError::Other("No keyboard hook on this platform".to_string()))
+    }
+
+    fn next_key(&mut self) -> Option<KeyInput> {
+        None
+    }
+}
+
+/// Picks the keys a hook takes from the operating system: a key that
+/// completes a combo in the local table with the modifiers held, a modifier
+/// the table has a tap for, and anything pressed while such a modifier is
+/// held. A taken key's release is taken too.
+#[derive(Debug, Default)]
+pub struct HookFilter {
+    held: Vec<KeyCode>,
+    taken: Vec<KeyCode>,
+}
+
+impl HookFilter {
+    /// Whether to take `key` from the operating system
+    pub fn take(&mut self, platform: Platform, key: KeyCode, pressed: bool) -> bool {
+        if !pressed {
+            self.held.retain(|k| *k != key);
+            let taken = self.taken.contains(&key);
+            self.taken.retain(|k| *k != key);
+            return taken;
+        }
+        if self.taken.contains(&key) {
+            return true;
+        }
+        let take = if is_modifier(key) {
+            if !self.held.contains(&key) {
+                self.held.push(key);
+            }
+            tables::local_action(platform, &[], key).is_some()
+        } else {
+            self.taken.iter().any(|k| is_modifier(*k)) || tables::local_action(platform, &self.held, key).is_some()
+        };
+        if take {
+            self.taken.push(key);
+        }
+        take
+    }
+}
+
+/// `named` if the input tables name the key, otherwise its native code when
+/// the shortcut tables list it, as a media key
+#[cfg_attr(not(any(windows, target_os = "macos")), allow(dead_code))]
+fn hook_key(platform: Platform, named: Option<KeyCode>, native: u32) -> Option<KeyCode> {
+    named.or_else(|| tables::is_table_key(platform, KeyCode::Raw(native)).then_some(KeyCode::Raw(native)))
+}
+
+/// Grabs the keyboard from the root window while active, on a connection of
+/// its own. Keys the input tables don't name are reported as their keysym,
+/// which only a Linux machine reads.
+#[cfg(target_os = "linux")]
+mod x11 {
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{ConnectionExt as _, GrabMode, GrabStatus};
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+    use x11rb::CURRENT_TIME;
+
+    use super::{KeyInput, KeyboardHook};
+    use crate::error::{GhostLinkError, Result};
+    use crate::input::{keycodes, KeyCode};
+
+    #[derive(Default)]
+    pub struct X11Hook {
+        grab: Option<Grab>,
+    }
+
+    struct Grab {
+        conn: RustConnection,
+        min_keycode: u8,
+        /// Key of each keycode from `min_keycode` on, by its unshifted keysym
+        keys: Vec<Option<KeyCode>>,
+    }
+
+    fn x11_error(e: impl std::fmt::Display) -> GhostLinkError {
+        GhostLinkError::Other(format!("X11: {}", e))
+    }
+
+    impl Grab {
+        fn start() -> Result<Self> {
+            if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+                return Err(GhostLinkError::Other(
+                    "Wayland doesn't let applications take system shortcuts".to_string(),
+                ));
+            }
+            let (conn, screen_num) = x11rb::connect(None).map_err(x11_error)?;
+            let setup = conn.setup();
+            let root = setup.roots[screen_num].root;
+            let (min_keycode, max_keycode) = (setup.min_keycode, setup.max_keycode);
+
+            let mapping = conn.get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)
+                .map_err(x11_error)?
+                .reply()
+                .map_err(x11_error)?;
+            let per_keycode = (mapping.keysyms_per_keycode as usize).max(1);
+            let keys = mapping.keysyms.chunks(per_keycode)
+                .map(|keysyms| match keysyms.first() {
+                    Some(0) | None => None,
+                    Some(keysym) => Some(keycodes::from_x11_keysym(*keysym).unwrap_or(KeyCode::Raw(*keysym))),
+                })
+                .collect();
+
+            let status = conn.grab_keyboard(false, root, CURRENT_TIME, GrabMode::ASYNC, GrabMode::ASYNC)
+                .map_err(x11_error)?
+                .reply()
+                .map_err(x11_error)?
+                .status;
+            if status != GrabStatus::SUCCESS {
+                return Err(GhostLinkError::Other(format!("X11 keyboard grab refused: {:?}", status)));
+            }
+            Ok(Self { conn, min_keycode, keys })
+        }
+
+        fn key(&self, keycode: u8) -> Option<KeyCode> {
+            self.keys.get(keycode.checked_sub(self.min_keycode)? as usize).copied().flatten()
+        }
+    }
+
+    impl KeyboardHook for X11Hook {
+        fn set_active(&mut self, active: bool) -> Result<()> {
+            if active == self.grab.is_some() {
+                return Ok(());
+            }
+            if active {
+                self.grab = Some(Grab::start()?);
+            } else if let Some(grab) = self.grab.take() {
+                let _ = grab.conn.ungrab_keyboard(CURRENT_TIME);
+                let _ = grab.conn.flush();
+            }
+            Ok(())
+        }
+
+        fn next_key(&mut self) -> Option<KeyInput> {
+            let grab = self.grab.as_ref()?;
+            while let Ok(Some(event)) = grab.conn.poll_for_event() {
+                let (keycode, pressed) = match event {
+                    Event::KeyPress(event) => (event.detail, true),
+                    Event::KeyRelease(event) => (event.detail, false),
+                    _ => continue,
+                };
+                if let Some(key) = grab.key(keycode) {
+                    return Some(KeyInput { key, pressed });
+                }
+            }
+            None
+        }
+
+        fn exclusive(&self) -> bool {
+            true
+        }
+    }
+}
+
+/// `WH_KEYBOARD_LL` hook on its own thread. Low-level hooks are called
+/// through the installing thread's message loop, so the thread only pumps
+/// messages until told to quit.
+#[cfg(windows)]
+mod win32 {
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use windows::Win32::Foundation::{HINSTANCE, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows::Win32::System::Threading::GetCurrentThreadId;
+    use windows::Win32::UI::WindowsAndMessaging::{
+        CallNextHookEx, GetMessageW, PostThreadMessageW, SetWindowsHookExW, UnhookWindowsHookEx, HC_ACTION, HHOOK,
+        KBDLLHOOKSTRUCT, LLKHF_EXTENDED, LLKHF_INJECTED, LLKHF_UP, MSG, WH_KEYBOARD_LL, WM_QUIT,
+    };
+
+    use super::{hook_key, HookFilter, KeyInput, KeyboardHook};
+    use crate::error::{GhostLinkError, Result};
+    use crate::input::keycodes;
+    use crate::session::shortcuts::Platform;
+
+    /// Where the hook procedure, which has no context argument, sends keys
+    static SINK: Mutex<Option<(HookFilter, mpsc::Sender<KeyInput>)>> = Mutex::new(None);
+
+    #[derive(Default)]
+    pub struct Win32Hook {
+        thread: Option<(u32, thread::JoinHandle<()>)>,
+        keys: Option<mpsc::Receiver<KeyInput>>,
+    }
+
+    extern "system" fn keyboard_proc(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        if code == HC_ACTION as i32 {
+            // SAFETY: for HC_ACTION, lparam points at the event's KBDLLHOOKSTRUCT
+            let event = unsafe { &*(lparam.0 as *const KBDLLHOOKSTRUCT) };
+            // Injected keys include the agent's own on this machine
+            if !event.flags.contains(LLKHF_INJECTED) {
+                let named = keycodes::from_windows_vk(event.vkCode as u16, event.flags.contains(LLKHF_EXTENDED));
+                let pressed = !event.flags.contains(LLKHF_UP);
+                if let Some(key) = hook_key(Platform::Windows, named, event.vkCode) {
+                    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+                    if let Some((filter, keys)) = sink.as_mut() {
+                        if filter.take(Platform::Windows, key, pressed) {
+                            let _ = keys.send(KeyInput { key, pressed });
+                            return LRESULT(1);
+                        }
+                    }
+                }
+            }
+        }
+        unsafe { CallNextHookEx(HHOOK::default(), code, wparam, lparam) }
+    }
+
+    /// Install the hook and pump messages until told to quit
+    fn run(ready: mpsc::Sender<Result<u32>>) {
+        let hook = unsafe {
+            GetModuleHandleW(None)
+                .and_then(|module| SetWindowsHookExW(WH_KEYBOARD_LL, Some(keyboard_proc), HINSTANCE(module.0), 0))
+        };
+        let hook = match hook {
+            Ok(hook) => hook,
+            Err(e) => {
+                let _ = ready.send(Err(GhostLinkError::Other(format!("SetWindowsHookExW failed: {}", e))));
+                return;
+            }
+        };
+        let _ = ready.send(Ok(unsafe { GetCurrentThreadId() }));
+
+        let mut message = MSG::default();
+        while unsafe { GetMessageW(&mut message, None, 0, 0) }.as_bool() {}
+        unsafe {
+            let _ = UnhookWindowsHookEx(hook);
+        }
+    }
+
+    impl Win32Hook {
+        fn start(&mut self) -> Result<()> {
+            let (keys_tx, keys_rx) = mpsc::channel();
+            *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some((HookFilter::default(), keys_tx));
+            let (ready_tx, ready_rx) = mpsc::channel();
+            let started = thread::Builder::new()
+                .name("keyboard-hook".to_string())
+                .spawn(move || run(ready_tx))
+                .map_err(GhostLinkError::from)
+                .and_then(|handle| {
+                    let thread_id = ready_rx.recv()
+                        .map_err(|_| GhostLinkError::Other("Keyboard hook thread exited".to_string()))??;
+                    Ok((thread_id, handle))
+                });
+            match started {
+                Ok(thread) => {
+                    self.thread = Some(thread);
+                    self.keys = Some(keys_rx);
+                    Ok(())
+                }
+                Err(e) => {
+                    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                    Err(e)
+                }
+            }
+        }
+
+        fn stop(&mut self) {
+            if let Some((thread_id, handle)) = self.thread.take() {
+                let _ = unsafe { PostThreadMessageW(thread_id, WM_QUIT, WPARAM(0), LPARAM(0)) };
+                let _ = handle.join();
+            }
+            *SINK.lock().unwrap_or_else(|e| e.into_inner()) = None;
+            self.keys = None;
+        }
+    }
+
+    impl KeyboardHook for Win32Hook {
+        fn set_active(&mut self, active: bool) -> Result<()> {
+            match (active, self.thread.is_some()) {
+                (true, false) => self.start(),
+                (false, true) => {
+                    self.stop();
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn next_key(&mut self) -> Option<KeyInput> {
+            self.keys.as_ref()?.try_recv().ok()
+        }
+    }
+}
+
+/// Session-level `CGEventTap` on a thread running its own run loop. Creating
+/// the tap needs the Accessibility permission.
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::ffi::c_void;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{hook_key, HookFilter, KeyInput, KeyboardHook};
+    use crate::error::{GhostLinkError, Result};
+    use crate::input::{keycodes, KeyCode};
+    use crate::session::shortcuts::Platform;
+
+    type CFTypeRef = *const c_void;
+    type EventRef = *mut c_void;
+    type TapCallback = extern "C" fn(*mut c_void, u32, EventRef, *mut c_void) -> EventRef;
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGEventTapCreate(
+            tap: u32,
+            place: u32,
+            options: u32,
+            events_of_interest: u64,
+            callback: TapCallback,
+            user_info: *mut c_void,
+        ) -> *mut c_void;
+        fn CGEventTapEnable(tap: *mut c_void, enable: bool);
+        fn CGEventGetIntegerValueField(event: EventRef, field: u32) -> i64;
+        fn CGEventGetFlags(event: EventRef) -> u64;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFMachPortCreateRunLoopSource(allocator: CFTypeRef, port: *mut c_void, order: isize) -> *mut c_void;
+        fn CFRunLoopGetCurrent() -> *mut c_void;
+        fn CFRunLoopAddSource(run_loop: *mut c_void, source: *mut c_void, mode: CFTypeRef);
+        fn CFRunLoopRun();
+        fn CFRunLoopStop(run_loop: *mut c_void);
+        fn CFRelease(object: CFTypeRef);
+        static kCFRunLoopCommonModes: CFTypeRef;
+    }
+
+    const SESSION_EVENT_TAP: u32 = 1;
+    const HEAD_INSERT_EVENT_TAP: u32 = 0;
+    const TAP_OPTION_DEFAULT: u32 = 0;
+    const KEY_DOWN: u32 = 10;
+    const KEY_UP: u32 = 11;
+    const FLAGS_CHANGED: u32 = 12;
+    const TAP_DISABLED_BY_TIMEOUT: u32 = 0xffff_fffe;
+    const TAP_DISABLED_BY_USER_INPUT: u32 = 0xffff_ffff;
+    const KEYBOARD_EVENT_KEYCODE: u32 = 9;
+
+    #[derive(Default)]
+    pub struct MacHook {
+        thread: Option<(usize, thread::JoinHandle<()>)>,
+        keys: Option<mpsc::Receiver<KeyInput>>,
+    }
+
+    struct Tap {
+        filter: HookFilter,
+        keys: mpsc::Sender<KeyInput>,
+        port: *mut c_void,
+    }
+
+    fn flag_mask(key: KeyCode) -> Option<u64> {
+        match key {
+            KeyCode::Shift => Some(0x2_0000),
+            KeyCode::Ctrl => Some(0x4_0000),
+            KeyCode::Alt => Some(0x8_0000),
+            KeyCode::Super => Some(0x10_0000),
+            _ => None,
+        }
+    }
+
+    extern "C" fn callback(_proxy: *mut c_void, event_type: u32, event: EventRef, user_info: *mut c_void) -> EventRef {
+        // SAFETY: user_info is the Tap owned by the run-loop thread calling this
+        let tap = unsafe { &mut *(user_info as *mut Tap) };
+        if event_type == TAP_DISABLED_BY_TIMEOUT || event_type == TAP_DISABLED_BY_USER_INPUT {
+            unsafe { CGEventTapEnable(tap.port, true) };
+            return event;
+        }
+        let code = unsafe { CGEventGetIntegerValueField(event, KEYBOARD_EVENT_KEYCODE) } as u16;
+        let Some(key) = hook_key(Platform::MacOs, keycodes::from_macos_keycode(code), code as u32) else {
+            return event;
+        };
+        let pressed = match event_type {
+            KEY_DOWN => true,
+            KEY_UP => false,
+            _ => match flag_mask(key) {
+                Some(mask) => (unsafe { CGEventGetFlags(event) } & mask) != 0,
+                None => return event,
+            },
+        };
+        if tap.filter.take(Platform::MacOs, key, pressed) {
+            let _ = tap.keys.send(KeyInput { key, pressed });
+            // A null event is dropped
+            return std::ptr::null_mut();
+        }
+        event
+    }
+
+    /// Create the tap and run its run loop until stopped
+    fn run(keys: mpsc::Sender<KeyInput>, ready: mpsc::Sender<Result<usize>>) {
+        let tap = Box::into_raw(Box::new(Tap { filter: HookFilter::default(), keys, port: std::ptr::null_mut() }));
+        let mask = (1 << KEY_DOWN) | (1 << KEY_UP) | (1 << FLAGS_CHANGED);
+        unsafe {
+            let port = CGEventTapCreate(
+                SESSION_EVENT_TAP,
+                HEAD_INSERT_EVENT_TAP,
+                TAP_OPTION_DEFAULT,
+                mask,
+                callback,
+                tap as *mut c_void,
+            );
+            if port.is_null() {
+                drop(Box::from_raw(tap));
+                let _ = ready.send(Err(GhostLinkError::Other(
+                    "Could not tap the keyboard; allow GhostLink under Privacy & Security > Accessibility".to_string(),
+                )));
+                return;
+            }
+            (*tap).port = port;
+            let source = CFMachPortCreateRunLoopSource(std::ptr::null(), port, 0);
+            let run_loop = CFRunLoopGetCurrent();
+            CFRunLoopAddSource(run_loop, source, kCFRunLoopCommonModes);
+            CGEventTapEnable(port, true);
+            let _ = ready.send(Ok(run_loop as usize));
+
+            CFRunLoopRun();
+
+            CGEventTapEnable(port, false);
+            CFRelease(source);
+            CFRelease(port);
+            drop(Box::from_raw(tap));
+        }
+    }
+
+    impl KeyboardHook for MacHook {
+        fn set_active(&mut self, active: bool) -> Result<()> {
+            if active == self.thread.is_some() {
+                return Ok(());
+            }
+            if active {
+                let (keys_tx, keys_rx) = mpsc::channel();
+                let (ready_tx, ready_rx) = mpsc::channel();
+                let handle = thread::Builder::new()
+                    .name("keyboard-tap".to_string())
+                    .spawn(move || run(keys_tx, ready_tx))?;
+                let run_loop = ready_rx.recv()
+                    .map_err(|_| GhostLinkError::Other("Keyboard tap thread exited".to_string()))??;
+                self.thread = Some((run_loop, handle));
+                self.keys = Some(keys_rx);
+            } else if let Some((run_loop, handle)) = self.thread.take() {
+                // A stop that lands before the loop runs is lost, so repeat it
+                while !handle.is_finished() {
+                    unsafe { CFRunLoopStop(run_loop as *mut c_void) };
+                    thread::sleep(Duration::from_millis(10));
+                }
+                let _ = handle.join();
+                self.keys = None;
+            }
+            Ok(())
+        }
+
+        fn next_key(&mut self) -> Option<KeyInput> {
+            self.keys.as_ref()?.try_recv().ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyCode::*;
+
+    #[test]
+    fn test_filter_takes_only_reserved_combos() {
+        let mut filter = HookFilter::default();
+        // Alt reaches the window; the Tab completing Alt+Tab doesn't
+        assert!(!filter.take(Platform::Windows, Alt, true));
+        assert!(filter.take(Platform::Windows, Tab, true));
+        assert!(filter.take(Platform::Windows, Tab, true));
+        assert!(filter.take(Platform::Windows, Tab, false));
+        assert!(!filter.take(Platform::Windows, A, true));
+        assert!(!filter.take(Platform::Windows, A, false));
+        assert!(!filter.take(Platform::Windows, Alt, false));
+        assert!(!filter.take(Platform::Windows, Tab, true));
+        assert!(!filter.take(Platform::Windows, Tab, false));
+    }
+
+    #[test]
+    fn test_filter_takes_everything_under_the_windows_key() {
+        let mut filter = HookFilter::default();
+        assert!(filter.take(Platform::Windows, Super, true));
+        assert!(filter.take(Platform::Windows, E, true));
+        assert!(filter.take(Platform::Windows, E, false));
+        assert!(filter.take(Platform::Windows, Super, false));
+        assert!(!filter.take(Platform::Windows, E, true));
+
+        // Cmd has no tap, so Cmd+C stays with the window
+        assert!(!filter.take(Platform::MacOs, Super, true));
+        assert!(!filter.take(Platform::MacOs, C, true));
+        assert!(filter.take(Platform::MacOs, Tab, true));
+    }
+
+    #[test]
+    fn test_hook_key_names_media_keys_from_the_table() {
+        assert_eq!(hook_key(Platform::Windows, Some(Tab), 0x09), Some(Tab));
+        assert_eq!(hook_key(Platform::Windows, None, 0xaf), Some(Raw(0xaf)));
+        assert_eq!(hook_key(Platform::Windows, None, 0xba), None);
+        assert_eq!(hook_key(Platform::MacOs, None, 0xaf), None);
+    }
+}
@@ -0,0 +1,688 @@
+//! Keyboard shortcuts in the session window
+//!
+//! Keys typed into the session window go to the remote machine, but the
+//! technician's own operating system keeps the combos it reserves: Alt+Tab,
+//! Cmd+Tab, the Windows key, the media keys. With "Send system shortcuts"
+//! on, a low-level keyboard hook takes them while the window has focus and
+//! [`ComboDetector`] turns them into what means the same on the remote
+//! machine, from the per-platform [`tables`]. Combos no hook can take,
+//! Ctrl+Alt+Del and Win+L, are toolbar buttons instead.
+//!
+//! When more than one session window is open, a combo that closes, locks or
+//! interrupts something waits for the technician to confirm which machine
+//! they meant, see [`guard`].
+
+pub mod guard;
+pub mod hook;
+pub mod tables;
+
+use tracing::{debug, info, warn};
+
+use crate::error::Result;
+use crate::input::{InputEvent, KeyCode};
+
+pub use guard::HotkeyGuard;
+pub use hook::KeyboardHook;
+pub use tables::RemoteShortcut;
+
+/// Keyboard conventions a shortcut table is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Windows,
+    MacOs,
+    Linux,
+}
+
+impl Platform {
+    /// The platform this viewer runs on
+    pub fn local() -> Self {
+        if cfg!(windows) {
+            Platform::Windows
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else {
+            Platform::Linux
+        }
+    }
+
+    /// Platform from a reported operating system name, e.g. "Windows 11 Pro"
+    /// or "Ubuntu 22.04"
+    pub fn from_os_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.contains("windows") {
+            Some(Platform::Windows)
+        } else if name.contains("mac") || name.contains("darwin") || name.contains("os x") {
+            Some(Platform::MacOs)
+        } else if ["linux", "ubuntu", "debian", "fedora", "centos", "red hat", "rhel", "suse", "arch"]
+            .iter()
+            .any(|distro| name.contains(distro))
+        {
+            Some(Platform::Linux)
+        } else {
+            None
+        }
+    }
+}
+
+/// Something a reserved combo does, whatever keys do it on a platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortcutAction {
+    SwitchApp,
+    SwitchAppBack,
+    /// Start menu, Spotlight or the desktop's launcher
+    Launcher,
+    TaskManager,
+    CloseApp,
+    LockScreen,
+    SecureAttention,
+    Screenshot,
+    VolumeUp,
+    VolumeDown,
+    VolumeMute,
+    MediaPlayPause,
+    MediaNext,
+    MediaPrevious,
+}
+
+impl ShortcutAction {
+    /// Combos no keyboard hook can take, sent from the toolbar
+    pub const TOOLBAR: [ShortcutAction; 3] =
+        [ShortcutAction::SecureAttention, ShortcutAction::LockScreen, ShortcutAction::Screenshot];
+
+    /// Whether sending this to the wrong machine would close, lock or
+    /// interrupt something there
+    pub fn is_destructive(self) -> bool {
+        matches!(self, ShortcutAction::CloseApp | ShortcutAction::LockScreen | ShortcutAction::SecureAttention)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ShortcutAction::SwitchApp => "Switch app",
+            ShortcutAction::SwitchAppBack => "Switch app back",
+            ShortcutAction::Launcher => "Launcher",
+            ShortcutAction::TaskManager => "Task manager",
+            ShortcutAction::CloseApp => "Close app",
+            ShortcutAction::LockScreen => "Lock screen",
+            ShortcutAction::SecureAttention => "Secure attention",
+            ShortcutAction::Screenshot => "Screenshot",
+            ShortcutAction::VolumeUp => "Volume up",
+            ShortcutAction::VolumeDown => "Volume down",
+            ShortcutAction::VolumeMute => "Mute",
+            ShortcutAction::MediaPlayPause => "Play/pause",
+            ShortcutAction::MediaNext => "Next track",
+            ShortcutAction::MediaPrevious => "Previous track",
+        }
+    }
+}
+
+/// What one local key event comes to
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyStep {
+    /// Forward these events
+    Send(Vec<InputEvent>),
+    /// The key completed a reserved combo
+    Shortcut(ShortcutAction),
+}
+
+pub fn is_modifier(key: KeyCode) -> bool {
+    matches!(key, KeyCode::Shift | KeyCode::Ctrl | KeyCode::Alt | KeyCode::Super)
+}
+
+/// Turns local key events into remote ones, spotting reserved combos on the
+/// way
+///
+/// Modifiers reach the remote machine only when a key that is not a
+/// shortcut is pressed with them, or when tapped on their own, so a local
+/// Cmd+Tab never shows up there as Cmd. Once a combo has been performed, the
+/// remote modifiers it holds stay down until every local modifier is
+/// released, so holding Alt and pressing Tab again keeps cycling.
+pub struct ComboDetector {
+    local: Platform,
+    remote: Platform,
+    /// Local modifiers held, in press order
+    held: Vec<KeyCode>,
+    /// Keys held on the remote machine, in press order
+    down: Vec<KeyCode>,
+    /// Keys whose release belongs to a combo
+    swallowed: Vec<KeyCode>,
+    /// Only one modifier pressed since it went down
+    clean: bool,
+    /// The remote modifiers are a combo's, not the local ones
+    translated: bool,
+}
+
+impl ComboDetector {
+    pub fn new(local: Platform, remote: Platform) -> Self {
+        Self {
+            local,
+            remote,
+            held: Vec::new(),
+            down: Vec::new(),
+            swallowed: Vec::new(),
+            clean: false,
+            translated: false,
+        }
+    }
+
+    pub fn remote(&self) -> Platform {
+        self.remote
+    }
+
+    /// One local key event
+    pub fn key(&mut self, key: KeyCode, pressed: bool) -> Option<KeyStep> {
+        let events = match (is_modifier(key), pressed) {
+            (true, true) => {
+                if !self.held.contains(&key) {
+                    // A tap is one modifier on its own
+                    self.clean = self.held.is_empty();
+                    self.held.push(key);
+                }
+                Vec::new()
+            }
+            (true, false) => return self.modifier_released(key),
+            (false, true) => {
+                self.clean = false;
+                if let Some(action) = tables::local_action(self.local, &self.held, key) {
+                    if !self.swallowed.contains(&key) {
+                        self.swallowed.push(key);
+                    }
+                    return Some(KeyStep::Shortcut(action));
+                }
+                let mut events = self.flush();
+                if !self.down.contains(&key) {
+                    self.down.push(key);
+                }
+                events.push(key_event(key, true));
+                events
+            }
+            (false, false) => {
+                if let Some(index) = self.swallowed.iter().position(|k| *k == key) {
+                    self.swallowed.remove(index);
+                    Vec::new()
+                } else if self.lift(key) {
+                    vec![key_event(key, false)]
+                } else {
+                    Vec::new()
+                }
+            }
+        };
+        (!events.is_empty()).then_some(KeyStep::Send(events))
+    }
+
+    fn modifier_released(&mut self, key: KeyCode) -> Option<KeyStep> {
+        let sole = self.held == [key];
+        self.held.retain(|k| *k != key);
+        let events = if self.translated {
+            if !self.held.is_empty() {
+                return None;
+            }
+            self.translated = false;
+            self.sync_modifiers(&[])
+        } else if self.lift(key) {
+            vec![key_event(key, false)]
+        } else if sole && self.clean {
+            // Tapped on its own without reaching the remote machine
+            self.clean = false;
+            if let Some(action) = tables::local_action(self.local, &[], key) {
+                return Some(KeyStep::Shortcut(action));
+            }
+            vec![key_event(key, true), key_event(key, false)]
+        } else {
+            Vec::new()
+        };
+        (!events.is_empty()).then_some(KeyStep::Send(events))
+    }
+
+    /// Press the held modifiers on the remote machine, e.g. before a click
+    /// that should be a Shift+click
+    pub fn flush(&mut self) -> Vec<InputEvent> {
+        self.translated = false;
+        let held = self.held.clone();
+        self.sync_modifiers(&held)
+    }
+
+    /// Text typed with the local keyboard layout
+    pub fn text(&mut self, text: &str) -> Vec<InputEvent> {
+        self.clean = false;
+        vec![InputEvent::TextInput { text: text.to_string() }]
+    }
+
+    /// Events performing `action` on the remote machine, none if it has no
+    /// way to
+    pub fn perform(&mut self, action: ShortcutAction) -> Vec<InputEvent> {
+        let (modifiers, key) = match tables::remote_shortcut(self.remote, action) {
+            None => return Vec::new(),
+            Some(RemoteShortcut::SecureAttention) => return vec![InputEvent::SecureAttention],
+            Some(RemoteShortcut::Keys { modifiers, key }) => (modifiers, key),
+        };
+        let mut events = self.sync_modifiers(modifiers);
+        events.push(key_event(key, true));
+        events.push(key_event(key, false));
+        if self.held.is_empty() {
+            events.extend(self.sync_modifiers(&[]));
+        } else {
+            self.translated = true;
+        }
+        debug!("Sent {} to the remote machine as {}", action.label(), tables::describe(self.remote, RemoteShortcut::Keys { modifiers, key }));
+        events
+    }
+
+    /// Release everything held on the remote machine, when the window loses
+    /// focus or the hook stops
+    pub fn release_all(&mut self) -> Vec<InputEvent> {
+        let events = self.down.drain(..).rev().map(|key| key_event(key, false)).collect();
+        self.held.clear();
+        self.swallowed.clear();
+        self.clean = false;
+        self.translated = false;
+        events
+    }
+
+    /// Bring the remote modifiers to exactly `target`
+    fn sync_modifiers(&mut self, target: &[KeyCode]) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        for key in self.down.iter().rev().filter(|k| is_modifier(**k) && !target.contains(k)) {
+            events.push(key_event(*key, false));
+        }
+        self.down.retain(|k| !is_modifier(*k) || target.contains(k));
+        for key in target {
+            if !self.down.contains(key) {
+                self.down.push(*key);
+                events.push(key_event(*key, true));
+            }
+        }
+        events
+    }
+
+    /// Take `key` off the remote machine's held keys, if it is there
+    fn lift(&mut self, key: KeyCode) -> bool {
+        let held = self.down.contains(&key);
+        self.down.retain(|k| *k != key);
+        held
+    }
+}
+
+fn key_event(key: KeyCode, pressed: bool) -> InputEvent {
+    InputEvent::KeyEvent { key, pressed }
+}
+
+/// Modifiers the session window reports; the Windows key only reaches it
+/// on macOS, as Cmd, and otherwise comes from the hook
+const WINDOW_MODIFIERS: &[KeyCode] = if cfg!(target_os = "macos") {
+    &[KeyCode::Shift, KeyCode::Ctrl, KeyCode::Alt, KeyCode::Super]
+} else {
+    &[KeyCode::Shift, KeyCode::Ctrl, KeyCode::Alt]
+};
+
+/// The session window's keyboard: the hook while passthrough is on and the
+/// window focused, the combo detector, and the guard's confirmation
+pub struct ShortcutLayer<H: KeyboardHook> {
+    hook: H,
+    detector: ComboDetector,
+    guard: HotkeyGuard,
+    passthrough: bool,
+    capturing: bool,
+    focused: bool,
+    confirming: Option<ShortcutAction>,
+}
+
+impl<H: KeyboardHook> ShortcutLayer<H> {
+    pub fn new(hook: H, detector: ComboDetector, guard: HotkeyGuard) -> Self {
+        Self {
+            hook,
+            detector,
+            guard,
+            passthrough: false,
+            capturing: false,
+            focused: false,
+            confirming: None,
+        }
+    }
+
+    pub fn passthrough(&self) -> bool {
+        self.passthrough
+    }
+
+    /// Turn "Send system shortcuts" on or off; the hook follows on the next
+    /// [`update`](Self::update)
+    pub fn set_passthrough(&mut self, passthrough: bool) {
+        self.passthrough = passthrough;
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capturing
+    }
+
+    /// Whether keys the window sees should be passed to [`key`](Self::key)
+    /// and [`modifiers`](Self::modifiers); not while a hook takes them all
+    pub fn window_keys(&self) -> bool {
+        !(self.capturing && self.hook.exclusive())
+    }
+
+    /// Follow the window's focus and drain the hook, once a frame. A hook
+    /// that fails to start turns passthrough off.
+    pub fn update(&mut self, focused: bool) -> Result<Vec<InputEvent>> {
+        let mut events = Vec::new();
+        if self.focused && !focused {
+            events.extend(self.detector.release_all());
+        }
+        self.focused = focused;
+
+        let wanted = self.passthrough && focused;
+        if wanted != self.capturing {
+            if let Err(e) = self.hook.set_active(wanted) {
+                warn!("Failed to capture system shortcuts: {}", e);
+                self.passthrough = false;
+                return Err(e);
+            }
+            self.capturing = wanted;
+            if !wanted {
+                events.extend(self.detector.release_all());
+            }
+        }
+        while self.capturing {
+            let Some(input) = self.hook.next_key() else { break };
+            events.extend(self.key(input.key, input.pressed));
+        }
+        Ok(events)
+    }
+
+    /// A key the window saw itself
+    pub fn key(&mut self, key: KeyCode, pressed: bool) -> Vec<InputEvent> {
+        match self.detector.key(key, pressed) {
+            None => Vec::new(),
+            Some(KeyStep::Send(events)) => events,
+            Some(KeyStep::Shortcut(action)) => self.request(action),
+        }
+    }
+
+    /// Bring the modifiers the window reports to the ones it says are held,
+    /// as it reports them with each key rather than as key events
+    pub fn modifiers(&mut self, held: &[KeyCode]) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        for &key in WINDOW_MODIFIERS {
+            let was_held = self.detector.held.contains(&key);
+            if was_held != held.contains(&key) {
+                events.extend(self.key(key, !was_held));
+            }
+        }
+        events
+    }
+
+    pub fn text(&mut self, text: &str) -> Vec<InputEvent> {
+        self.detector.text(text)
+    }
+
+    /// A toolbar button
+    pub fn press(&mut self, action: ShortcutAction) -> Vec<InputEvent> {
+        self.request(action)
+    }
+
+    /// How the remote machine does `action`, or `None` if it can't
+    pub fn remote_label(&self, action: ShortcutAction) -> Option<String> {
+        let remote = self.detector.remote();
+        tables::remote_shortcut(remote, action).map(|shortcut| tables::describe(remote, shortcut))
+    }
+
+    /// Action waiting for the technician to confirm
+    pub fn confirming(&self) -> Option<ShortcutAction> {
+        self.confirming
+    }
+
+    pub fn confirm(&mut self) -> Vec<InputEvent> {
+        match self.confirming.take() {
+            Some(action) => self.detector.perform(action),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        if let Some(action) = self.confirming.take() {
+            info!("{} not sent", action.label());
+        }
+    }
+
+    fn request(&mut self, action: ShortcutAction) -> Vec<InputEvent> {
+        if self.guard.needs_confirmation(action) {
+            self.confirming = Some(action);
+            return Vec::new();
+        }
+        self.detector.perform(action)
+    }
+}
+
+impl<H: KeyboardHook> Drop for ShortcutLayer<H> {
+    fn drop(&mut self) {
+        if self.capturing {
+            let _ = self.hook.set_active(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GhostLinkError;
+    use hook::KeyInput;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use KeyCode::*;
+
+    fn press(key: KeyCode) -> InputEvent {
+        key_event(key, true)
+    }
+
+    fn release(key: KeyCode) -> InputEvent {
+        key_event(key, false)
+    }
+
+    fn sent(events: &[InputEvent]) -> Option<KeyStep> {
+        Some(KeyStep::Send(events.to_vec()))
+    }
+
+    /// Hook fed from the test, recording when it was switched
+    #[derive(Clone, Default)]
+    struct MockHook {
+        keys: Arc<Mutex<VecDeque<KeyInput>>>,
+        calls: Arc<Mutex<Vec<bool>>>,
+        failing: Arc<Mutex<bool>>,
+    }
+
+    impl KeyboardHook for MockHook {
+        fn set_active(&mut self, active: bool) -> Result<()> {
+            self.calls.lock().unwrap().push(active);
+            if active && *self.failing.lock().unwrap() {
+                return Err(GhostLinkError::Other("no accessibility permission".to_string()));
+            }
+            Ok(())
+        }
+
+        fn next_key(&mut self) -> Option<KeyInput> {
+            self.keys.lock().unwrap().pop_front()
+        }
+    }
+
+    impl MockHook {
+        fn type_keys(&self, keys: &[(KeyCode, bool)]) {
+            self.keys.lock().unwrap().extend(keys.iter().map(|(key, pressed)| KeyInput { key: *key, pressed: *pressed }));
+        }
+    }
+
+    #[test]
+    fn test_platform_from_os_name() {
+        assert_eq!(Platform::from_os_name("Windows 11 Pro"), Some(Platform::Windows));
+        assert_eq!(Platform::from_os_name("macOS 14.2"), Some(Platform::MacOs));
+        assert_eq!(Platform::from_os_name("Ubuntu 22.04.3 LTS"), Some(Platform::Linux));
+        assert_eq!(Platform::from_os_name("Unknown"), None);
+    }
+
+    #[test]
+    fn test_plain_keys_carry_their_modifiers() {
+        let mut detector = ComboDetector::new(Platform::Windows, Platform::Windows);
+        assert_eq!(detector.key(Ctrl, true), None);
+        assert_eq!(detector.key(C, true), sent(&[press(Ctrl), press(C)]));
+        assert_eq!(detector.key(C, false), sent(&[release(C)]));
+        assert_eq!(detector.key(C, true), sent(&[press(C)]));
+        assert_eq!(detector.key(C, false), sent(&[release(C)]));
+        assert_eq!(detector.key(Ctrl, false), sent(&[release(Ctrl)]));
+
+        // A modifier tapped on its own is forwarded as a tap
+        assert_eq!(detector.key(Alt, true), None);
+        assert_eq!(detector.key(Alt, false), sent(&[press(Alt), release(Alt)]));
+        // Two modifiers pressed and released together send nothing
+        detector.key(Ctrl, true);
+        detector.key(Shift, true);
+        assert_eq!(detector.key(Shift, false), None);
+        assert_eq!(detector.key(Ctrl, false), None);
+    }
+
+    #[test]
+    fn test_alt_tab_keeps_cycling_while_alt_is_held() {
+        let mut detector = ComboDetector::new(Platform::Windows, Platform::Windows);
+        assert_eq!(detector.key(Alt, true), None);
+        assert_eq!(detector.key(Tab, true), Some(KeyStep::Shortcut(ShortcutAction::SwitchApp)));
+        assert_eq!(detector.perform(ShortcutAction::SwitchApp), [press(Alt), press(Tab), release(Tab)]);
+        assert_eq!(detector.key(Tab, false), None);
+
+        // Shift joins for a step back, then leaves again
+        assert_eq!(detector.key(Shift, true), None);
+        assert_eq!(detector.key(Tab, true), Some(KeyStep::Shortcut(ShortcutAction::SwitchAppBack)));
+        assert_eq!(detector.perform(ShortcutAction::SwitchAppBack), [press(Shift), press(Tab), release(Tab)]);
+        assert_eq!(detector.key(Tab, false), None);
+        assert_eq!(detector.key(Shift, false), None);
+        assert_eq!(detector.key(Tab, true), Some(KeyStep::Shortcut(ShortcutAction::SwitchApp)));
+        assert_eq!(detector.perform(ShortcutAction::SwitchApp), [release(Shift), press(Tab), release(Tab)]);
+        assert_eq!(detector.key(Tab, false), None);
+
+        assert_eq!(detector.key(Alt, false), sent(&[release(Alt)]));
+        assert_eq!(detector.release_all(), []);
+    }
+
+    #[test]
+    fn test_mac_combos_become_windows_ones() {
+        let mut detector = ComboDetector::new(Platform::MacOs, Platform::Windows);
+        detector.key(Super, true);
+        assert_eq!(detector.key(Tab, true), Some(KeyStep::Shortcut(ShortcutAction::SwitchApp)));
+        // The remote machine never sees Cmd
+        assert_eq!(detector.perform(ShortcutAction::SwitchApp), [press(Alt), press(Tab), release(Tab)]);
+        detector.key(Tab, false);
+        assert_eq!(detector.key(Super, false), sent(&[release(Alt)]));
+
+        detector.key(Super, true);
+        assert_eq!(detector.key(Space, true), Some(KeyStep::Shortcut(ShortcutAction::Launcher)));
+        assert_eq!(detector.perform(ShortcutAction::Launcher), [press(Super), release(Super)]);
+        detector.key(Space, false);
+        assert_eq!(detector.key(Super, false), None);
+
+        // Cmd+C is not a shortcut and goes through as it is
+        detector.key(Super, true);
+        assert_eq!(detector.key(C, true), sent(&[press(Super), press(C)]));
+    }
+
+    #[test]
+    fn test_windows_key_tap_opens_the_launcher() {
+        let mut detector = ComboDetector::new(Platform::Windows, Platform::MacOs);
+        assert_eq!(detector.key(Super, true), None);
+        assert_eq!(detector.key(Super, false), Some(KeyStep::Shortcut(ShortcutAction::Launcher)));
+        assert_eq!(
+            detector.perform(ShortcutAction::Launcher),
+            [press(Super), press(Space), release(Space), release(Super)]
+        );
+
+        // Win+E is not a tap
+        detector.key(Super, true);
+        assert_eq!(detector.key(E, true), sent(&[press(Super), press(E)]));
+        detector.key(E, false);
+        assert_eq!(detector.key(Super, false), sent(&[release(Super)]));
+
+        assert_eq!(detector.perform(ShortcutAction::SecureAttention), []);
+    }
+
+    #[test]
+    fn test_release_all_lifts_every_remote_key() {
+        let mut detector = ComboDetector::new(Platform::Linux, Platform::Linux);
+        detector.key(Shift, true);
+        detector.key(A, true);
+        detector.key(Down, true);
+        assert_eq!(detector.release_all(), [release(Down), release(A), release(Shift)]);
+        // The releases that follow have nothing left to lift
+        assert_eq!(detector.key(A, false), None);
+        assert_eq!(detector.key(Shift, false), None);
+    }
+
+    #[test]
+    fn test_layer_follows_focus_and_drains_the_hook() {
+        let hook = MockHook::default();
+        let mut layer = ShortcutLayer::new(
+            hook.clone(),
+            ComboDetector::new(Platform::Windows, Platform::Windows),
+            HotkeyGuard::unguarded(),
+        );
+        assert_eq!(layer.update(true).unwrap(), []);
+        assert!(!layer.is_capturing());
+
+        layer.set_passthrough(true);
+        hook.type_keys(&[(Alt, true), (Tab, true), (Tab, false)]);
+        assert_eq!(layer.update(true).unwrap(), [press(Alt), press(Tab), release(Tab)]);
+        assert!(layer.is_capturing());
+
+        // Focus leaves mid-combo; the remote Alt is released
+        assert_eq!(layer.update(false).unwrap(), [release(Alt)]);
+        assert!(!layer.is_capturing());
+        assert_eq!(*hook.calls.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn test_layer_turns_passthrough_off_when_the_hook_fails() {
+        let hook = MockHook::default();
+        *hook.failing.lock().unwrap() = true;
+        let mut layer = ShortcutLayer::new(
+            hook.clone(),
+            ComboDetector::new(Platform::MacOs, Platform::MacOs),
+            HotkeyGuard::unguarded(),
+        );
+        layer.set_passthrough(true);
+        assert!(layer.update(true).is_err());
+        assert!(!layer.passthrough());
+        assert!(!layer.is_capturing());
+        assert_eq!(layer.update(true).unwrap(), []);
+        assert_eq!(*hook.calls.lock().unwrap(), [true]);
+    }
+
+    #[test]
+    fn test_layer_asks_before_destructive_combos() {
+        let mut layer = ShortcutLayer::new(
+            MockHook::default(),
+            ComboDetector::new(Platform::Windows, Platform::Windows),
+            HotkeyGuard::confirming(),
+        );
+        assert_eq!(layer.press(ShortcutAction::Screenshot), [press(PrintScreen), release(PrintScreen)]);
+        assert_eq!(layer.remote_label(ShortcutAction::LockScreen).unwrap(), "Win+L");
+
+        assert_eq!(layer.press(ShortcutAction::SecureAttention), []);
+        assert_eq!(layer.confirming(), Some(ShortcutAction::SecureAttention));
+        assert_eq!(layer.confirm(), [InputEvent::SecureAttention]);
+        assert_eq!(layer.confirming(), None);
+
+        layer.key(Alt, true);
+        assert_eq!(layer.key(F4, true), []);
+        assert_eq!(layer.confirming(), Some(ShortcutAction::CloseApp));
+        layer.cancel();
+        assert_eq!(layer.key(F4, false), []);
+        assert_eq!(layer.key(Alt, false), []);
+        assert_eq!(layer.confirm(), []);
+    }
+
+    #[test]
+    fn test_layer_syncs_window_modifiers() {
+        let mut layer = ShortcutLayer::new(
+            MockHook::default(),
+            ComboDetector::new(Platform::Linux, Platform::Linux),
+            HotkeyGuard::unguarded(),
+        );
+        assert_eq!(layer.modifiers(&[Ctrl]), []);
+        assert_eq!(layer.key(S, true), [press(Ctrl), press(S)]);
+        layer.key(S, false);
+        assert_eq!(layer.modifiers(&[]), [release(Ctrl)]);
+    }
+}
@@ -0,0 +1,307 @@
+//! Shortcut tables, one per platform
+//!
+//! `local` lists the combos a technician presses on their own keyboard and
+//! what they mean there; `remote` lists the keys that mean the same on the
+//! machine being controlled. Passing Cmd+Tab from a Mac to a Windows machine
+//! is a lookup in each. Media keys have no `KeyCode`, so they are listed as
+//! the platform's native code in `KeyCode::Raw`, which the agent injects
+//! as-is.
+
+use super::{Platform, ShortcutAction};
+use crate::input::KeyCode;
+
+use KeyCode::*;
+use ShortcutAction::*;
+
+/// A combo on the technician's keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalShortcut {
+    /// Modifiers held, in any order
+    pub modifiers: &'static [KeyCode],
+    /// Key pressed while they are held. A modifier here means a tap of that
+    /// modifier on its own.
+    pub key: KeyCode,
+    pub action: ShortcutAction,
+}
+
+/// How the remote machine is told to perform an action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteShortcut {
+    /// Hold `modifiers` in order, tap `key`, release them. With no modifiers
+    /// and a modifier as `key`, a tap of that modifier.
+    Keys { modifiers: &'static [KeyCode], key: KeyCode },
+    /// Ctrl+Alt+Del through the agent's secure attention sequence
+    SecureAttention,
+}
+
+/// Both tables for one platform
+pub struct PlatformShortcuts {
+    pub local: &'static [LocalShortcut],
+    pub remote: &'static [(ShortcutAction, RemoteShortcut)],
+}
+
+const fn local(modifiers: &'static [KeyCode], key: KeyCode, action: ShortcutAction) -> LocalShortcut {
+    LocalShortcut { modifiers, key, action }
+}
+
+const fn keys(modifiers: &'static [KeyCode], key: KeyCode) -> RemoteShortcut {
+    RemoteShortcut::Keys { modifiers, key }
+}
+
+/// Windows virtual-key codes of the media keys
+mod vk {
+    pub const VOLUME_MUTE: u32 = 0xad;
+    pub const VOLUME_DOWN: u32 = 0xae;
+    pub const VOLUME_UP: u32 = 0xaf;
+    pub const MEDIA_NEXT: u32 = 0xb0;
+    pub const MEDIA_PREVIOUS: u32 = 0xb1;
+    pub const MEDIA_PLAY_PAUSE: u32 = 0xb3;
+}
+
+/// XF86 keysyms of the media keys
+mod xf86 {
+    pub const AUDIO_LOWER_VOLUME: u32 = 0x1008_ff11;
+    pub const AUDIO_MUTE: u32 = 0x1008_ff12;
+    pub const AUDIO_RAISE_VOLUME: u32 = 0x1008_ff13;
+    pub const AUDIO_PLAY: u32 = 0x1008_ff14;
+    pub const AUDIO_PREV: u32 = 0x1008_ff16;
+    pub const AUDIO_NEXT: u32 = 0x1008_ff17;
+}
+
+/// macOS virtual key codes of the volume keys. Play and the track keys are
+/// system-defined events rather than key codes, so they have no entry.
+mod kvk {
+    pub const VOLUME_UP: u32 = 0x48;
+    pub const VOLUME_DOWN: u32 = 0x49;
+    pub const MUTE: u32 = 0x4a;
+}
+
+/// Ctrl+Alt+Del and Win+L never reach a keyboard hook on Windows, so they
+/// have no local row; the toolbar sends them.
+pub static WINDOWS: PlatformShortcuts = PlatformShortcuts {
+    local: &[
+        local(&[Alt], Tab, SwitchApp),
+        local(&[Alt, Shift], Tab, SwitchAppBack),
+        local(&[], Super, Launcher),
+        local(&[Ctrl], Escape, Launcher),
+        local(&[Ctrl, Shift], Escape, TaskManager),
+        local(&[Alt], F4, CloseApp),
+        local(&[], PrintScreen, Screenshot),
+        local(&[], Raw(vk::VOLUME_UP), VolumeUp),
+        local(&[], Raw(vk::VOLUME_DOWN), VolumeDown),
+        local(&[], Raw(vk::VOLUME_MUTE), VolumeMute),
+        local(&[], Raw(vk::MEDIA_PLAY_PAUSE), MediaPlayPause),
+        local(&[], Raw(vk::MEDIA_NEXT), MediaNext),
+        local(&[], Raw(vk::MEDIA_PREVIOUS), MediaPrevious),
+    ],
+    remote: &[
+        (SwitchApp, keys(&[Alt], Tab)),
+        (SwitchAppBack, keys(&[Alt, Shift], Tab)),
+        (Launcher, keys(&[], Super)),
+        (TaskManager, keys(&[Ctrl, Shift], Escape)),
+        (CloseApp, keys(&[Alt], F4)),
+        (LockScreen, keys(&[Super], L)),
+        (SecureAttention, RemoteShortcut::SecureAttention),
+        (Screenshot, keys(&[], PrintScreen)),
+        (VolumeUp, keys(&[], Raw(vk::VOLUME_UP))),
+        (VolumeDown, keys(&[], Raw(vk::VOLUME_DOWN))),
+        (VolumeMute, keys(&[], Raw(vk::VOLUME_MUTE))),
+        (MediaPlayPause, keys(&[], Raw(vk::MEDIA_PLAY_PAUSE))),
+        (MediaNext, keys(&[], Raw(vk::MEDIA_NEXT))),
+        (MediaPrevious, keys(&[], Raw(vk::MEDIA_PREVIOUS))),
+    ],
+};
+
+/// Cmd is `Super` and Option is `Alt`. A Mac has no secure attention
+/// sequence.
+pub static MACOS: PlatformShortcuts = PlatformShortcuts {
+    local: &[
+        local(&[Super], Tab, SwitchApp),
+        local(&[Super, Shift], Tab, SwitchAppBack),
+        local(&[Super], Space, Launcher),
+        local(&[Super, Alt], Escape, TaskManager),
+        local(&[Super], Q, CloseApp),
+        local(&[Ctrl, Super], Q, LockScreen),
+        local(&[Super, Shift], Key3, Screenshot),
+    ],
+    remote: &[
+        (SwitchApp, keys(&[Super], Tab)),
+        (SwitchAppBack, keys(&[Super, Shift], Tab)),
+        (Launcher, keys(&[Super], Space)),
+        (TaskManager, keys(&[Super, Alt], Escape)),
+        (CloseApp, keys(&[Super], Q)),
+        (LockScreen, keys(&[Ctrl, Super], Q)),
+        (Screenshot, keys(&[Super, Shift], Key3)),
+        (VolumeUp, keys(&[], Raw(kvk::VOLUME_UP))),
+        (VolumeDown, keys(&[], Raw(kvk::VOLUME_DOWN))),
+        (VolumeMute, keys(&[], Raw(kvk::MUTE))),
+    ],
+};
+
+/// The common GNOME and KDE bindings. The X server's own Ctrl+Alt+Del is
+/// only a key combo, so it is sent as one.
+pub static LINUX: PlatformShortcuts = PlatformShortcuts {
+    local: &[
+        local(&[Alt], Tab, SwitchApp),
+        local(&[Alt, Shift], Tab, SwitchAppBack),
+        local(&[], Super, Launcher),
+        local(&[Super], L, LockScreen),
+        local(&[Ctrl, Alt], Delete, SecureAttention),
+        local(&[Alt], F4, CloseApp),
+        local(&[], PrintScreen, Screenshot),
+        local(&[], Raw(xf86::AUDIO_RAISE_VOLUME), VolumeUp),
+        local(&[], Raw(xf86::AUDIO_LOWER_VOLUME), VolumeDown),
+        local(&[], Raw(xf86::AUDIO_MUTE), VolumeMute),
+        local(&[], Raw(xf86::AUDIO_PLAY), MediaPlayPause),
+        local(&[], Raw(xf86::AUDIO_NEXT), MediaNext),
+        local(&[], Raw(xf86::AUDIO_PREV), MediaPrevious),
+    ],
+    remote: &[
+        (SwitchApp, keys(&[Alt], Tab)),
+        (SwitchAppBack, keys(&[Alt, Shift], Tab)),
+        (Launcher, keys(&[], Super)),
+        (CloseApp, keys(&[Alt], F4)),
+        (LockScreen, keys(&[Super], L)),
+        (SecureAttention, keys(&[Ctrl, Alt], Delete)),
+        (Screenshot, keys(&[], PrintScreen)),
+        (VolumeUp, keys(&[], Raw(xf86::AUDIO_RAISE_VOLUME))),
+        (VolumeDown, keys(&[], Raw(xf86::AUDIO_LOWER_VOLUME))),
+        (VolumeMute, keys(&[], Raw(xf86::AUDIO_MUTE))),
+        (MediaPlayPause, keys(&[], Raw(xf86::AUDIO_PLAY))),
+        (MediaNext, keys(&[], Raw(xf86::AUDIO_NEXT))),
+        (MediaPrevious, keys(&[], Raw(xf86::AUDIO_PREV))),
+    ],
+};
+
+pub fn shortcuts(platform: Platform) -> &'static PlatformShortcuts {
+    match platform {
+        Platform::Windows => &WINDOWS,
+        Platform::MacOs => &MACOS,
+        Platform::Linux => &LINUX,
+    }
+}
+
+/// Action for `key` pressed with exactly `held` on `platform`
+pub fn local_action(platform: Platform, held: &[KeyCode], key: KeyCode) -> Option<ShortcutAction> {
+    shortcuts(platform).local.iter()
+        .find(|row| row.key == key && same_keys(row.modifiers, held))
+        .map(|row| row.action)
+}
+
+/// Keys performing `action` on `platform`, if it has them
+pub fn remote_shortcut(platform: Platform, action: ShortcutAction) -> Option<RemoteShortcut> {
+    shortcuts(platform).remote.iter()
+        .find(|(a, _)| *a == action)
+        .map(|(_, shortcut)| *shortcut)
+}
+
+/// Whether a `Raw` key is one the tables know on `platform`. Other raw keys
+/// are left to the operating system.
+pub fn is_table_key(platform: Platform, key: KeyCode) -> bool {
+    shortcuts(platform).local.iter().any(|row| row.key == key)
+}
+
+/// How `shortcut` reads on `platform`'s keyboard, e.g. "Ctrl+Alt+Del"
+pub fn describe(platform: Platform, shortcut: RemoteShortcut) -> String {
+    match shortcut {
+        RemoteShortcut::SecureAttention => "Ctrl+Alt+Del".to_string(),
+        RemoteShortcut::Keys { modifiers, key } => modifiers.iter()
+            .chain(std::iter::once(&key))
+            .map(|key| key_name(platform, *key))
+            .collect::<Vec<_>>()
+            .join("+"),
+    }
+}
+
+fn key_name(platform: Platform, key: KeyCode) -> String {
+    let name = match (platform, key) {
+        (Platform::Windows, Super) => "Win",
+        (Platform::MacOs, Super) => "Cmd",
+        (Platform::Linux, Super) => "Super",
+        (Platform::MacOs, Alt) => "Option",
+        (_, Delete) => "Del",
+        (_, Escape) => "Esc",
+        (_, PrintScreen) => "PrtScn",
+        (_, Raw(_)) => "Media",
+        (_, key) => {
+            let name = format!("{:?}", key);
+            return name.strip_prefix("Key").filter(|digit| !digit.is_empty()).unwrap_or(&name).to_string();
+        }
+    };
+    name.to_string()
+}
+
+fn same_keys(a: &[KeyCode], b: &[KeyCode]) -> bool {
+    a.len() == b.len() && a.iter().all(|key| b.contains(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLATFORMS: [Platform; 3] = [Platform::Windows, Platform::MacOs, Platform::Linux];
+
+    fn is_modifier(key: KeyCode) -> bool {
+        matches!(key, Shift | Ctrl | Alt | Super)
+    }
+
+    #[test]
+    fn test_tables_are_unambiguous() {
+        for platform in PLATFORMS {
+            let table = shortcuts(platform);
+            for (i, row) in table.local.iter().enumerate() {
+                assert!(row.modifiers.iter().all(|m| is_modifier(*m)), "{:?} {:?}", platform, row);
+                assert!(!row.modifiers.contains(&row.key), "{:?} {:?}", platform, row);
+                assert!(
+                    table.local[..i].iter().all(|other| other.key != row.key || !same_keys(other.modifiers, row.modifiers)),
+                    "{:?} lists {:?} twice", platform, row
+                );
+            }
+            for (i, (action, shortcut)) in table.remote.iter().enumerate() {
+                assert!(table.remote[..i].iter().all(|(other, _)| other != action), "{:?} {:?}", platform, action);
+                if let RemoteShortcut::Keys { modifiers, key } = shortcut {
+                    assert!(modifiers.iter().all(|m| is_modifier(*m)), "{:?} {:?}", platform, action);
+                    assert!(modifiers.is_empty() || !is_modifier(*key), "{:?} {:?}", platform, action);
+                }
+            }
+            // Every action pressed locally has a counterpart on the same platform
+            for row in table.local {
+                assert!(remote_shortcut(platform, row.action).is_some(), "{:?} {:?}", platform, row.action);
+            }
+        }
+    }
+
+    #[test]
+    fn test_local_combos_map_across_platforms() {
+        let switch = local_action(Platform::MacOs, &[Super], Tab).unwrap();
+        assert_eq!(switch, SwitchApp);
+        assert_eq!(remote_shortcut(Platform::Windows, switch), Some(keys(&[Alt], Tab)));
+
+        // Modifier order doesn't matter, but extra modifiers do
+        assert_eq!(local_action(Platform::Windows, &[Shift, Alt], Tab), Some(SwitchAppBack));
+        assert_eq!(local_action(Platform::Windows, &[Ctrl, Alt], Tab), None);
+        assert_eq!(local_action(Platform::Windows, &[], Super), Some(Launcher));
+        assert_eq!(remote_shortcut(Platform::MacOs, Launcher), Some(keys(&[Super], Space)));
+
+        assert_eq!(remote_shortcut(Platform::Windows, SecureAttention), Some(RemoteShortcut::SecureAttention));
+        assert_eq!(remote_shortcut(Platform::Linux, SecureAttention), Some(keys(&[Ctrl, Alt], Delete)));
+        assert_eq!(remote_shortcut(Platform::MacOs, SecureAttention), None);
+        assert_eq!(remote_shortcut(Platform::Linux, TaskManager), None);
+
+        let volume = local_action(Platform::Linux, &[], Raw(xf86::AUDIO_RAISE_VOLUME)).unwrap();
+        assert_eq!(remote_shortcut(Platform::Windows, volume), Some(keys(&[], Raw(vk::VOLUME_UP))));
+        assert!(is_table_key(Platform::Windows, Raw(vk::MEDIA_NEXT)));
+        assert!(!is_table_key(Platform::Windows, Raw(0x41)));
+    }
+
+    #[test]
+    fn test_describe_uses_the_platform_names() {
+        let lock = remote_shortcut(Platform::Windows, LockScreen).unwrap();
+        assert_eq!(describe(Platform::Windows, lock), "Win+L");
+        assert_eq!(describe(Platform::Windows, RemoteShortcut::SecureAttention), "Ctrl+Alt+Del");
+        assert_eq!(describe(Platform::Windows, remote_shortcut(Platform::Windows, Screenshot).unwrap()), "PrtScn");
+        assert_eq!(describe(Platform::MacOs, remote_shortcut(Platform::MacOs, Screenshot).unwrap()), "Cmd+Shift+3");
+        assert_eq!(describe(Platform::MacOs, remote_shortcut(Platform::MacOs, TaskManager).unwrap()), "Cmd+Option+Esc");
+        assert_eq!(describe(Platform::Linux, remote_shortcut(Platform::Linux, SecureAttention).unwrap()), "Ctrl+Alt+Del");
+    }
+}
@@ -0,0 +1,197 @@
+//! Session join tokens
+//!
+//! The server signs a short-lived Ed25519 token when a technician opens a
+//! session and embeds it in the launch URL. The session window checks it
+//! against the server's published key before attaching, so a stale or
+//! copied link fails here instead of half-opening a window.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::{ConnectionError, Context, Result, SessionError};
+
+/// Purpose claim the server puts in every session token
+const SESSION_TOKEN_PURPOSE: &str = "session-join";
+
+/// What a verified token lets this window do
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SessionGrant {
+    pub session_id: String,
+    pub user_id: String,
+    /// view, control, file or terminal
+    pub scopes: Vec<String>,
+    pub exp: i64,
+}
+
+impl SessionGrant {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.exp, 0).unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct TokenClaims {
+    purpose: String,
+    #[serde(flatten)]
+    grant: SessionGrant,
+}
+
+#[derive(Deserialize)]
+struct TokenKey {
+    public_key: String,
+}
+
+/// Where the server publishes the key session tokens are signed with
+pub fn key_url(server_url: &str) -> Result<Url> {
+    let invalid = |reason: String| ConnectionError::InvalidServerUrl { url: server_url.to_string(), reason };
+    let mut url = Url::parse(server_url).map_err(|e| invalid(e.to_string()))?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        other => return Err(invalid(format!("unsupported scheme {}", other)).into()),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| invalid("cannot be turned into an HTTP URL".to_string()))?;
+    url.set_path("/api/session-tokens/key");
+    url.set_query(None);
+    Ok(url)
+}
+
+/// Check a token's signature, purpose, session and expiry
+pub fn verify(token: &str, public_key: &[u8], session_id: &str, now: DateTime<Utc>) -> Result<SessionGrant> {
+    let rejected = |reason: String| SessionError::InvalidToken { session_id: session_id.to_string(), reason };
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(rejected("malformed".to_string()).into());
+    };
+    let header: TokenHeader = URL_SAFE_NO_PAD.decode(header).ok()
+        .and_then(|header| serde_json::from_slice(&header).ok())
+        .ok_or_else(|| rejected("header is malformed".to_string()))?;
+    if header.alg != "EdDSA" {
+        return Err(rejected(format!("signed with {}, expected EdDSA", header.alg)).into());
+    }
+    let signed = &token[..token.len() - signature.len() - 1];
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| rejected("signature is malformed".to_string()))?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(signed.as_bytes(), &signature)
+        .map_err(|_| rejected("signature does not match the server's key".to_string()))?;
+
+    let claims: TokenClaims = URL_SAFE_NO_PAD.decode(payload).ok()
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+        .ok_or_else(|| rejected("claims are malformed".to_string()))?;
+    if claims.purpose != SESSION_TOKEN_PURPOSE {
+        return Err(rejected("not a session token".to_string()).into());
+    }
+    if claims.grant.session_id != session_id {
+        return Err(rejected(format!("issued for session {}", claims.grant.session_id)).into());
+    }
+    if claims.grant.exp <= now.timestamp() {
+        return Err(rejected(format!("expired at {}", claims.grant.expires_at())).into());
+    }
+    Ok(claims.grant)
+}
+
+/// Fetch the server's key and verify `token` for `session_id` with it
+pub async fn validate(server_url: &str, session_id: &str, token: &str) -> Result<SessionGrant> {
+    let url = key_url(server_url)?;
+    let key: TokenKey = crate::connection::proxy::http_client()
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Server returned an unreadable session token key")?;
+    let public_key = URL_SAFE_NO_PAD.decode(&key.public_key).context("Session token key is not base64")?;
+    verify(token, &public_key, session_id, Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GhostLinkError;
+    use chrono::Duration;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn keypair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn sign(keypair: &Ed25519KeyPair, claims: serde_json::Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"EdDSA"}"#);
+        let signed = format!("{}.{}", header, URL_SAFE_NO_PAD.encode(claims.to_string()));
+        let signature = URL_SAFE_NO_PAD.encode(keypair.sign(signed.as_bytes()));
+        format!("{}.{}", signed, signature)
+    }
+
+    fn claims(session_id: &str, exp: DateTime<Utc>) -> serde_json::Value {
+        serde_json::json!({
+            "purpose": "session-join",
+            "session_id": session_id,
+            "user_id": "7f0c9a52-1d7e-4c55-9d3c-5a6f1b1d2e3f",
+            "scopes": ["view"],
+            "jti": "b1c8f0e2-3a4d-4e5f-8a9b-0c1d2e3f4a5b",
+            "iat": exp.timestamp() - 900,
+            "exp": exp.timestamp(),
+        })
+    }
+
+    #[test]
+    fn test_verify_checks_signature_session_and_expiry() {
+        let keypair = keypair();
+        let public_key = keypair.public_key().as_ref();
+        let now = Utc::now();
+        let token = sign(&keypair, claims("session-1", now + Duration::minutes(15)));
+
+        let grant = verify(&token, public_key, "session-1", now).unwrap();
+        assert!(grant.allows("view") && !grant.allows("control"));
+        assert!(matches!(
+            verify(&token, public_key, "session-2", now),
+            Err(GhostLinkError::Session(SessionError::InvalidToken { session_id, .. })) if session_id == "session-2"
+        ));
+        assert!(verify(&token, public_key, "session-1", now + Duration::minutes(15)).is_err());
+        assert!(verify(&token, self::keypair().public_key().as_ref(), "session-1", now).is_err());
+
+        let mut other = claims("session-1", now + Duration::minutes(15));
+        other["purpose"] = "relay-node".into();
+        assert!(verify(&sign(&keypair, other), public_key, "session-1", now).is_err());
+        assert!(verify("not-a-token", public_key, "session-1", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_uses_the_servers_key() {
+        let keypair = keypair();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/session-tokens/key"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "algorithm": "EdDSA",
+                "public_key": URL_SAFE_NO_PAD.encode(keypair.public_key().as_ref()),
+            })))
+            .mount(&server)
+            .await;
+        let server_url = server.uri().replacen("http", "ws", 1);
+        let token = sign(&keypair, claims("session-1", Utc::now() + Duration::minutes(15)));
+
+        assert_eq!(validate(&server_url, "session-1", &token).await.unwrap().scopes, vec!["view"]);
+        assert!(validate(&server_url, "session-2", &token).await.is_err());
+        assert_eq!(key_url("wss://relay.example.com/ws").unwrap().as_str(), "https://relay.example.com/api/session-tokens/key");
+        assert!(key_url("ftp://relay.example.com").is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Health supervision of a session's capture, encoder and input
+//!
+//! Capturers, encoders and input handlers report `is_healthy()`, but lose
+//! their backing resource without telling anyone: an X server connection
+//! drops, the portal revokes the screencast, a GPU encoder resets. The
+//! watchdog polls them every few seconds and restarts whichever failed, a
+//! bounded number of times with a growing pause in between. Each step is a
+//! [`HealthEvent`], which the agent relays to the viewer so it shows
+//! "recovering video" instead of a frozen picture, and a component that
+//! cannot be brought back ends the session with the last error as reason.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::error::Result;
+
+/// Part of a session the watchdog restarts on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    Capture,
+    Encoder,
+    Input,
+}
+
+impl Component {
+    /// In the order they are checked; a new capturer may fix the encoder's
+    /// input, so it goes first
+    pub const ALL: [Component; 3] = [Component::Capture, Component::Encoder, Component::Input];
+}
+
+impl std::fmt::Display for Component {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Component::Capture => "screen capture",
+            Component::Encoder => "video encoder",
+            Component::Input => "input injection",
+        })
+    }
+}
+
+/// How often components are checked and how hard they are restarted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchdogPolicy {
+    /// Seconds between health checks; 0 turns the watchdog off
+    pub check_interval_secs: u64,
+    /// Restarts tried on a failed component before the session ends
+    pub max_restarts: u32,
+    /// Pause before the second restart, doubled for each one after
+    pub backoff_secs: u64,
+    /// Longest pause between restarts
+    pub max_backoff_secs: u64,
+}
+
+impl Default for WatchdogPolicy {
+    fn default() -> Self {
+        Self {
+            check_interval_secs: 3,
+            max_restarts: 3,
+            backoff_secs: 1,
+            max_backoff_secs: 15,
+        }
+    }
+}
+
+impl WatchdogPolicy {
+    pub fn check_interval(&self) -> Option<Duration> {
+        (self.check_interval_secs > 0).then(|| Duration::from_secs(self.check_interval_secs))
+    }
+
+    /// Pause before restart `attempt`, counting from 1; the first is
+    /// tried right away
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        if attempt <= 1 {
+            return Duration::ZERO;
+        }
+        let secs = self.backoff_secs.saturating_mul(1u64 << (attempt - 2).min(16));
+        Duration::from_secs(secs.min(self.max_backoff_secs))
+    }
+}
+
+/// A step of the watchdog, relayed to the viewer as `SessionHealth`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum HealthEvent {
+    /// `component` failed its check and restart `attempt` is under way
+    Recovering { component: Component, attempt: u32, reason: String },
+    /// `component` passed its check again after a restart
+    Recovered { component: Component },
+    /// No restart brought `component` back; the session ends
+    Failed { component: Component, reason: String },
+}
+
+/// What the watchdog checks and restarts; the session in the agent, mocks
+/// in tests
+#[async_trait]
+pub trait Supervised: Send + Sync {
+    /// Whether the session still runs; the watchdog stops once it doesn't
+    async fn is_running(&self) -> bool;
+
+    async fn is_healthy(&self, component: Component) -> bool;
+
+    /// Replace `component` with a fresh instance, releasing the old one
+    async fn restart(&self, component: Component) -> Result<()>;
+}
+
+/// Check `target` every interval until it stops running, or until a
+/// component cannot be restarted; that component and the last error come
+/// back then
+pub async fn supervise<S, F>(target: &S, policy: &WatchdogPolicy, mut on_event: F) -> Option<(Component, String)>
+where
+    S: Supervised + ?Sized,
+    F: FnMut(HealthEvent),
+{
+    let interval = policy.check_interval()?;
+    loop {
+        tokio::time::sleep(interval).await;
+        if !target.is_running().await {
+            return None;
+        }
+
+        for component in Component::ALL {
+            if target.is_healthy(component).await {
+                continue;
+            }
+            warn!("Session {} failed its health check", component);
+            if let Some(reason) = recover(target, policy, component, &mut on_event).await? {
+                on_event(HealthEvent::Failed { component, reason: reason.clone() });
+                return Some((component, reason));
+            }
+        }
+    }
+}
+
+/// Restart `component` until it is healthy or the restarts run out, in
+/// which case the last error comes back. `None` means the session stopped
+/// meanwhile.
+async fn recover<S, F>(target: &S, policy: &WatchdogPolicy, component: Component, on_event: &mut F) -> Option<Option<String>>
+where
+    S: Supervised + ?Sized,
+    F: FnMut(HealthEvent),
+{
+    let mut reason = format!("{} stopped responding", component);
+    for attempt in 1..=policy.max_restarts {
+        on_event(HealthEvent::Recovering { component, attempt, reason: reason.clone() });
+        tokio::time::sleep(policy.backoff(attempt)).await;
+        if !target.is_running().await {
+            return None;
+        }
+
+        match target.restart(component).await {
+            Ok(()) if target.is_healthy(component).await => {
+                info!("Session {} recovered after {} restart(s)", component, attempt);
+                on_event(HealthEvent::Recovered { component });
+                return Some(None);
+            }
+            Ok(()) => reason = format!("{} still unhealthy after a restart", component),
+            Err(e) => reason = format!("Restarting {} failed: {}", component, e),
+        }
+        warn!("{} (attempt {} of {})", reason, attempt, policy.max_restarts);
+    }
+    Some(Some(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    /// Components that fail their check until restarted `fixed_after` times;
+    /// restarts listed in `broken_restarts` return an error instead
+    #[derive(Default)]
+    struct MockSession {
+        running: Mutex<bool>,
+        unhealthy: Mutex<HashMap<Component, u32>>,
+        broken_restarts: Mutex<u32>,
+        restarts: Mutex<Vec<(Component, Instant)>>,
+    }
+
+    impl MockSession {
+        fn failing(component: Component, fixed_after: u32) -> Self {
+            let session = Self { running: Mutex::new(true), ..Self::default() };
+            session.unhealthy.lock().unwrap().insert(component, fixed_after);
+            session
+        }
+    }
+
+    #[async_trait]
+    impl Supervised for MockSession {
+        async fn is_running(&self) -> bool {
+            *self.running.lock().unwrap()
+        }
+
+        async fn is_healthy(&self, component: Component) -> bool {
+            !self.unhealthy.lock().unwrap().contains_key(&component)
+        }
+
+        async fn restart(&self, component: Component) -> Result<()> {
+            self.restarts.lock().unwrap().push((component, Instant::now()));
+            let mut broken = self.broken_restarts.lock().unwrap();
+            if *broken > 0 {
+                *broken -= 1;
+                return Err("display is gone".into());
+            }
+            let mut unhealthy = self.unhealthy.lock().unwrap();
+            if let Some(remaining) = unhealthy.get_mut(&component) {
+                *remaining = remaining.saturating_sub(1);
+                if *remaining == 0 {
+                    unhealthy.remove(&component);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn states(events: &[HealthEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| match event {
+                HealthEvent::Recovering { component, attempt, .. } => format!("recovering {:?} {}", component, attempt),
+                HealthEvent::Recovered { component } => format!("recovered {:?}", component),
+                HealthEvent::Failed { component, .. } => format!("failed {:?}", component),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let policy = WatchdogPolicy::default();
+        let pauses: Vec<u64> = (1..=6).map(|attempt| policy.backoff(attempt).as_secs()).collect();
+        assert_eq!(pauses, [0, 1, 2, 4, 8, 15]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart_then_recover() {
+        let session = std::sync::Arc::new(MockSession::failing(Component::Capture, 2));
+        let policy = WatchdogPolicy::default();
+        let events = std::sync::Arc::new(Mutex::new(Vec::new()));
+
+        let watched = std::sync::Arc::clone(&session);
+        let seen = std::sync::Arc::clone(&events);
+        let watchdog = tokio::spawn(async move {
+            supervise(&*watched, &policy, |event| seen.lock().unwrap().push(event)).await
+        });
+
+        // One check, a restart that leaves it unhealthy, then one after a second
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(
+            states(&events.lock().unwrap()),
+            ["recovering Capture 1", "recovering Capture 2", "recovered Capture"]
+        );
+        let restarts = session.restarts.lock().unwrap().clone();
+        assert_eq!(restarts.len(), 2);
+        assert_eq!(restarts[1].1 - restarts[0].1, Duration::from_secs(1));
+
+        // The session ending stops the watchdog at its next check
+        *session.running.lock().unwrap() = false;
+        assert_eq!(watchdog.await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_after_max_restarts() {
+        let session = MockSession::failing(Component::Input, u32::MAX);
+        *session.broken_restarts.lock().unwrap() = u32::MAX;
+        let policy = WatchdogPolicy { max_restarts: 3, ..WatchdogPolicy::default() };
+        let mut events = Vec::new();
+
+        let started = Instant::now();
+        let outcome = supervise(&session, &policy, |event| events.push(event)).await;
+
+        let (component, reason) = outcome.expect("the watchdog gave up");
+        assert_eq!(component, Component::Input);
+        assert!(reason.contains("display is gone"), "{}", reason);
+        assert_eq!(
+            states(&events),
+            ["recovering Input 1", "recovering Input 2", "recovering Input 3", "failed Input"]
+        );
+        // The check interval, then pauses of 0, 1 and 2 seconds
+        assert_eq!(started.elapsed(), Duration::from_secs(3 + 1 + 2));
+        assert_eq!(session.restarts.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_healthy_session_is_left_alone() {
+        let session = MockSession { running: Mutex::new(true), ..MockSession::default() };
+        let policy = WatchdogPolicy::default();
+        let mut events = Vec::new();
+
+        let outcome = tokio::time::timeout(
+            Duration::from_secs(60),
+            supervise(&session, &policy, |event| events.push(event)),
+        )
+        .await;
+        assert!(outcome.is_err(), "still watching");
+        assert!(events.is_empty());
+        assert!(session.restarts.lock().unwrap().is_empty());
+
+        let off = WatchdogPolicy { check_interval_secs: 0, ..WatchdogPolicy::default() };
+        assert_eq!(supervise(&session, &off, |_| {}).await, None);
+    }
+}
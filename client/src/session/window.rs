@@ -1,12 +1,31 @@
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{broadcast, RwLock};
 use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::toolbox::ToolboxManager;
+use crate::connection::proxy::http_client;
+use crate::elevation::ElevatedContext;
+use crate::error::{GhostLinkError, Result, ToolError};
+use crate::file_transfer::transfer::TransferState;
+use crate::file_transfer::TransferProgress;
+use crate::recording::SessionRecorder;
+use crate::registry::{RegistryData, RegistryHive, RegistryKeyInfo, RegistryValue};
+use crate::toolbox::execution::{self, ExecutionLimits, ToolExecutionHandle, ToolExitStatus, ToolOutputEvent};
+use crate::toolbox::{Tool, ToolboxManager};
+
+use super::commands::{self, CommandHistory, DEFAULT_MAX_LENGTH, DEFAULT_TIMEOUT_SECS};
+use super::event_sync::{EventSender, EventSyncConfig};
+use super::events::SessionEventKind;
+use super::notes::{self, NoteTemplate, SessionEnd};
+pub use super::commands::CommandExecution;
+
+/// Extra output collected past `#maxlength`, so the line that crosses the
+/// limit still arrives and is cut at the exact byte
+const OUTPUT_SLACK: usize = 64 * 1024;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionInfo {
@@ -19,7 +38,7 @@ pub struct SessionInfo {
     pub last_activity: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SessionTab {
     Start,      // Main remote desktop view
     General,    // Session information
@@ -29,6 +48,18 @@ pub enum SessionTab {
     Notes,      // Session notes
 }
 
+impl SessionTab {
+    /// Tabs in the order the window shows them
+    pub const ALL: [SessionTab; 6] = [
+        SessionTab::Start,
+        SessionTab::General,
+        SessionTab::Timeline,
+        SessionTab::Messages,
+        SessionTab::Commands,
+        SessionTab::Notes,
+    ];
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: Uuid,
@@ -45,6 +76,14 @@ pub struct SessionNote {
     pub author: String,
     pub timestamp: chrono::DateTime<chrono::Utc>,
     pub is_private: bool,
+    /// Organization template the note was started from
+    #[serde(default)]
+    pub template_id: Option<Uuid>,
+    /// Written to close the session out
+    #[serde(default)]
+    pub closeout: bool,
+    #[serde(default)]
+    pub edited_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,35 +95,90 @@ pub struct TimelineEvent {
     pub details: HashMap<String, String>,
 }
 
+/// A key in the General tab's registry browser
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CommandExecution {
-    pub id: Uuid,
-    pub command: String,
-    pub output: String,
-    pub exit_code: Option<i32>,
-    pub execution_time: chrono::DateTime<chrono::Utc>,
-    pub duration_ms: u64,
-    pub timeout_seconds: u32,
-    pub max_length: usize,
-    pub shell: Option<String>,
+pub struct RegistryTreeNode {
+    pub path: String,
+    pub name: String,
+    pub expanded: bool,
+    pub values: Vec<RegistryValue>,
+    pub children: Vec<RegistryTreeNode>,
+}
+
+impl RegistryTreeNode {
+    fn new(path: String, name: String) -> Self {
+        Self {
+            path,
+            name,
+            expanded: false,
+            values: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+    
+    fn find_mut(&mut self, path: &str) -> Option<&mut RegistryTreeNode> {
+        if self.path.eq_ignore_ascii_case(path) {
+            return Some(self);
+        }
+        self.children.iter_mut().find_map(|child| child.find_mut(path))
+    }
+}
+
+/// Everything the window shows, copied out at one point in time
+#[derive(Debug, Clone)]
+pub struct WindowSnapshot {
+    pub session_info: SessionInfo,
+    pub backstage_mode: bool,
+    pub input_suspended: bool,
+    pub screen_blanked: bool,
+    pub recording: bool,
+    pub tools: Vec<Tool>,
+    pub messages: Vec<ChatMessage>,
+    pub notes: Vec<SessionNote>,
+    pub note_templates: Vec<NoteTemplate>,
+    pub ticket_ref: Option<String>,
+    /// The server would not end the session without a close-out note
+    pub closeout_required: bool,
+    pub ended: bool,
+    pub timeline: Vec<TimelineEvent>,
+    pub commands: Vec<CommandExecution>,
+    pub transfers: Vec<TransferProgress>,
 }
 
 pub struct SessionWindow {
     pub session_info: SessionInfo,
-    pub current_tab: SessionTab,
-    pub toolbox: Arc<Mutex<ToolboxManager>>,
+    current_tab: parking_lot::Mutex<SessionTab>,
+    pub toolbox: Arc<ToolboxManager>,
+    /// Tools for the sidebar, refreshed whenever the toolbox changes
+    pub toolbox_tools: Arc<RwLock<Vec<Tool>>>,
     
     // Tab data
     pub messages: Arc<RwLock<Vec<ChatMessage>>>,
     pub notes: Arc<RwLock<Vec<SessionNote>>>,
+    /// Templates of the technician's organization for the Notes tab
+    pub note_templates: Arc<RwLock<Vec<NoteTemplate>>>,
+    ticket_ref: RwLock<Option<String>>,
     pub timeline: Arc<RwLock<Vec<TimelineEvent>>>,
+    /// Results from the session's history file, plus this window's runs
     pub command_history: Arc<RwLock<Vec<CommandExecution>>>,
+    pub registry_tree: Arc<RwLock<Vec<RegistryTreeNode>>>,
+    pub file_transfers: Arc<RwLock<HashMap<String, TransferProgress>>>,
     
-    // Session state
-    pub is_backstage_mode: bool,
-    pub input_suspended: bool,
-    pub screen_blanked: bool,
+    // Session state; the control buttons flip these while commands run
+    is_backstage_mode: AtomicBool,
+    input_suspended: AtomicBool,
+    screen_blanked: AtomicBool,
+    closeout_required: AtomicBool,
+    ended: AtomicBool,
     pub is_recording: bool,
+    recorder: Option<Arc<SessionRecorder>>,
+    /// Rights commands run with, once the server's PAM flow elevated the session
+    elevation: Option<ElevatedContext>,
+    history: CommandHistory,
+    /// Technician shown as running the tab's commands
+    technician: String,
+    /// Timeline events on their way to the server
+    events: Option<EventSender>,
     
     // Connection
     pub server_url: String,
@@ -96,7 +190,19 @@ impl SessionWindow {
         session_id: String,
         server_url: String,
         auth_token: String,
-        toolbox: ToolboxManager,
+        toolbox: Arc<ToolboxManager>,
+    ) -> Result<Self> {
+        let history = CommandHistory::for_session(&session_id);
+        Self::with_history(session_id, server_url, auth_token, toolbox, history).await
+    }
+    
+    /// Window whose Commands tab keeps its history in `history`
+    pub async fn with_history(
+        session_id: String,
+        server_url: String,
+        auth_token: String,
+        toolbox: Arc<ToolboxManager>,
+        history: CommandHistory,
     ) -> Result<Self> {
         let session_info = SessionInfo {
             session_id: session_id.clone(),
@@ -107,71 +213,150 @@ impl SessionWindow {
             connected_time: chrono::Utc::now(),
             last_activity: chrono::Utc::now(),
         };
+        let previous_commands = history.load().await.unwrap_or_else(|e| {
+            warn!("Command history unavailable: {}", e);
+            Vec::new()
+        });
+        let events = match Uuid::parse_str(&session_id) {
+            Ok(id) => Some(EventSender::spawn(&server_url, id, auth_token.clone(), EventSyncConfig::default())),
+            Err(_) => {
+                warn!("Session ID {} is not a UUID; its timeline stays local", session_id);
+                None
+            }
+        };
+        
+        let toolbox_tools = Arc::new(RwLock::new(toolbox.list_tools()));
+        Self::watch_toolbox(&toolbox, Arc::clone(&toolbox_tools));
         
         Ok(Self {
             session_info,
-            current_tab: SessionTab::Start,
-            toolbox: Arc::new(Mutex::new(toolbox)),
+            current_tab: parking_lot::Mutex::new(SessionTab::Start),
+            toolbox,
+            toolbox_tools,
             messages: Arc::new(RwLock::new(Vec::new())),
             notes: Arc::new(RwLock::new(Vec::new())),
+            note_templates: Arc::new(RwLock::new(Vec::new())),
+            ticket_ref: RwLock::new(None),
             timeline: Arc::new(RwLock::new(Vec::new())),
-            command_history: Arc::new(RwLock::new(Vec::new())),
-            is_backstage_mode: false,
-            input_suspended: false,
-            screen_blanked: false,
+            command_history: Arc::new(RwLock::new(previous_commands)),
+            registry_tree: Arc::new(RwLock::new(Self::registry_roots())),
+            file_transfers: Arc::new(RwLock::new(HashMap::new())),
+            is_backstage_mode: AtomicBool::new(false),
+            input_suspended: AtomicBool::new(false),
+            screen_blanked: AtomicBool::new(false),
+            closeout_required: AtomicBool::new(false),
+            ended: AtomicBool::new(false),
             is_recording: false,
+            recorder: None,
+            elevation: None,
+            history,
+            technician: token_email(&auth_token).unwrap_or_else(|| "Technician".to_string()),
+            events,
             server_url,
             auth_token,
         })
     }
     
-    pub async fn switch_tab(&mut self, tab: SessionTab) {
+    pub async fn switch_tab(&self, tab: SessionTab) {
         info!("Switching to tab: {:?}", tab);
-        self.current_tab = tab;
+        let previous = std::mem::replace(&mut *self.current_tab.lock(), tab);
+        // Only the web timeline shows tab switches
+        if let Some(events) = &self.events {
+            let details = serde_json::json!({ "from": format!("{:?}", previous), "to": format!("{:?}", tab) });
+            events.emit(SessionEventKind::TabSwitch, &self.technician, details);
+        }
+    }
+    
+    pub fn current_tab(&self) -> SessionTab {
+        *self.current_tab.lock()
+    }
+    
+    /// Technician shown as the author of the window's commands and notes
+    pub fn technician(&self) -> &str {
+        &self.technician
+    }
+    
+    pub fn is_backstage_mode(&self) -> bool {
+        self.is_backstage_mode.load(Ordering::Relaxed)
     }
     
-    pub async fn enable_backstage_mode(&mut self) -> Result<()> {
+    pub fn input_suspended(&self) -> bool {
+        self.input_suspended.load(Ordering::Relaxed)
+    }
+    
+    pub fn screen_blanked(&self) -> bool {
+        self.screen_blanked.load(Ordering::Relaxed)
+    }
+    
+    pub async fn enable_backstage_mode(&self) -> Result<()> {
         info!("Enabling backstage mode for session {}", self.session_info.session_id);
-        self.is_backstage_mode = true;
+        self.is_backstage_mode.store(true, Ordering::Relaxed);
         self.add_timeline_event("backstage_enabled", "Backstage mode enabled").await;
         Ok(())
     }
     
-    pub async fn disable_backstage_mode(&mut self) -> Result<()> {
+    pub async fn disable_backstage_mode(&self) -> Result<()> {
         info!("Disabling backstage mode for session {}", self.session_info.session_id);
-        self.is_backstage_mode = false;
+        self.is_backstage_mode.store(false, Ordering::Relaxed);
         self.add_timeline_event("backstage_disabled", "Backstage mode disabled").await;
         Ok(())
     }
     
-    pub async fn suspend_input(&mut self) -> Result<()> {
+    pub async fn suspend_input(&self) -> Result<()> {
         info!("Suspending remote input");
-        self.input_suspended = true;
+        self.input_suspended.store(true, Ordering::Relaxed);
         self.add_timeline_event("input_suspended", "Remote input suspended").await;
         Ok(())
     }
     
-    pub async fn resume_input(&mut self) -> Result<()> {
+    /// Run later Commands tab commands elevated, or with the helper's own rights again
+    pub async fn set_elevation(&mut self, context: Option<ElevatedContext>) {
+        match &context {
+            Some(context) => {
+                info!("Commands now run as {} via {}", context.level, context.method);
+                let details = HashMap::from([
+                    ("level".to_string(), context.level.to_string()),
+                    ("method".to_string(), context.method.to_string()),
+                ]);
+                self.add_timeline_event_with_details("elevation_granted", &format!("Elevated to {}", context.level), details).await;
+            }
+            None => {
+                if let Some(previous) = &self.elevation {
+                    self.add_timeline_event("elevation_ended", &format!("Elevation to {} ended", previous.level)).await;
+                }
+            }
+        }
+        self.elevation = context;
+    }
+    
+    pub async fn resume_input(&self) -> Result<()> {
         info!("Resuming remote input");
-        self.input_suspended = false;
+        self.input_suspended.store(false, Ordering::Relaxed);
         self.add_timeline_event("input_resumed", "Remote input resumed").await;
         Ok(())
     }
     
-    pub async fn blank_screen(&mut self) -> Result<()> {
+    pub async fn blank_screen(&self) -> Result<()> {
         info!("Blanking remote screen");
-        self.screen_blanked = true;
+        self.screen_blanked.store(true, Ordering::Relaxed);
         self.add_timeline_event("screen_blanked", "Remote screen blanked").await;
         Ok(())
     }
     
-    pub async fn unblank_screen(&mut self) -> Result<()> {
+    pub async fn unblank_screen(&self) -> Result<()> {
         info!("Unblanking remote screen");
-        self.screen_blanked = false;
+        self.screen_blanked.store(false, Ordering::Relaxed);
         self.add_timeline_event("screen_unblanked", "Remote screen restored").await;
         Ok(())
     }
     
+    /// Append chat, commands and tool runs to the session's recording
+    pub async fn attach_recorder(&mut self, recorder: Arc<SessionRecorder>) {
+        self.recorder = Some(recorder);
+        self.is_recording = true;
+        self.add_timeline_event("recording_started", "Session recording started").await;
+    }
+    
     pub async fn send_message(&self, message: String, is_technician: bool) -> Result<()> {
         let chat_message = ChatMessage {
             id: Uuid::new_v4(),
@@ -181,25 +366,144 @@ impl SessionWindow {
             is_technician,
         };
         
+        if let Some(recorder) = &self.recorder {
+            recorder.record_chat(&chat_message.sender, &chat_message.message);
+        }
+        let actor = if is_technician { self.technician.clone() } else { chat_message.sender.clone() };
+        let details = HashMap::from([("message".to_string(), chat_message.message.clone())]);
         self.messages.write().await.push(chat_message);
-        self.add_timeline_event("message_sent", "Chat message sent").await;
+        self.record_timeline_event("message_sent", "Chat message sent", details, &actor).await;
         Ok(())
     }
     
-    pub async fn add_note(&self, content: String, author: String, is_private: bool) -> Result<()> {
+    /// Add a note, started from `template_id` if given. Notes of sessions
+    /// the server knows are only kept once it has stored them.
+    pub async fn add_note(&self, content: String, author: String, is_private: bool, template_id: Option<Uuid>) -> Result<()> {
         let note = SessionNote {
             id: Uuid::new_v4(),
             content,
             author,
             timestamp: chrono::Utc::now(),
             is_private,
+            template_id,
+            closeout: false,
+            edited_at: None,
         };
+        self.store_note(note).await
+    }
+    
+    /// Write the close-out note and end the session
+    pub async fn close_out(&self, content: String, template_id: Option<Uuid>) -> Result<SessionEnd> {
+        let note = SessionNote {
+            id: Uuid::new_v4(),
+            content,
+            author: self.technician.clone(),
+            timestamp: chrono::Utc::now(),
+            is_private: false,
+            template_id,
+            closeout: true,
+            edited_at: None,
+        };
+        self.store_note(note).await?;
+        self.end_session().await
+    }
+    
+    async fn store_note(&self, note: SessionNote) -> Result<()> {
+        if self.on_server() {
+            notes::upload(&self.server_url, &self.session_info.session_id, &self.auth_token, &note).await?;
+        }
         
+        // Private notes stay out of the shared timeline's details
+        let mut details = HashMap::from([("private".to_string(), note.is_private.to_string())]);
+        if !note.is_private {
+            details.insert("content".to_string(), note.content.clone());
+        }
+        let (event_type, description) = if note.closeout {
+            ("closeout_note_added", "Close-out note added")
+        } else {
+            ("note_added", "Session note added")
+        };
+        let author = note.author.clone();
         self.notes.write().await.push(note);
-        self.add_timeline_event("note_added", "Session note added").await;
+        self.record_timeline_event(event_type, description, details, &author).await;
+        Ok(())
+    }
+    
+    /// Replace the text of one of the session's notes
+    pub async fn edit_note(&self, note_id: Uuid, content: String) -> Result<()> {
+        if !self.notes.read().await.iter().any(|note| note.id == note_id) {
+            return Err(GhostLinkError::Other(format!("Note {} is not in this session", note_id)));
+        }
+        if self.on_server() {
+            notes::edit(&self.server_url, &self.session_info.session_id, &self.auth_token, note_id, &content).await?;
+        }
+        if let Some(note) = self.notes.write().await.iter_mut().find(|note| note.id == note_id) {
+            note.content = content;
+            note.edited_at = Some(chrono::Utc::now());
+        }
+        let details = HashMap::from([("note_id".to_string(), note_id.to_string())]);
+        self.add_timeline_event_with_details("note_edited", "Session note edited", details).await;
+        Ok(())
+    }
+    
+    /// Link the session to a ticket in the organization's PSA; blank unlinks it
+    pub async fn set_ticket_ref(&self, ticket_ref: &str) -> Result<()> {
+        let ticket_ref = Some(ticket_ref.trim()).filter(|ticket_ref| !ticket_ref.is_empty());
+        let stored = if self.on_server() {
+            notes::set_ticket(&self.server_url, &self.session_info.session_id, &self.auth_token, ticket_ref).await?
+        } else {
+            ticket_ref.map(str::to_string)
+        };
+        let details = HashMap::from([("ticket_ref".to_string(), stored.clone().unwrap_or_default())]);
+        *self.ticket_ref.write().await = stored;
+        self.add_timeline_event_with_details("ticket_linked", "Ticket linked", details).await;
         Ok(())
     }
     
+    /// Load the note templates of the technician's organization
+    pub async fn refresh_note_templates(&self) -> Result<()> {
+        if !self.on_server() {
+            return Ok(());
+        }
+        let templates = notes::templates(&self.server_url, &self.auth_token).await?;
+        *self.note_templates.write().await = templates;
+        Ok(())
+    }
+    
+    /// Ask the server to end the session. When the policy wants a close-out
+    /// note first, the session stays open and the snapshot says so.
+    pub async fn end_session(&self) -> Result<SessionEnd> {
+        let end = if self.on_server() {
+            notes::end(&self.server_url, &self.session_info.session_id, &self.auth_token).await?
+        } else {
+            SessionEnd::Ended
+        };
+        match end {
+            SessionEnd::Ended => {
+                self.ended.store(true, Ordering::Relaxed);
+                self.add_timeline_event("session_ended", "Session ended").await;
+            }
+            SessionEnd::CloseoutRequired => {
+                info!("Session {} needs a close-out note before it ends", self.session_info.session_id);
+                self.closeout_required.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(end)
+    }
+    
+    /// Whether the session is one the server keeps; sessions with an ID
+    /// other than a UUID are local only
+    fn on_server(&self) -> bool {
+        self.events.is_some()
+    }
+    
+    /// Run text typed into the Commands tab, honouring its leading
+    /// `#timeout`, `#maxlength` and `#!shell` modifiers
+    pub async fn run_command(&self, text: &str) -> Result<CommandExecution> {
+        let parsed = commands::parse_command(text)?;
+        self.execute_command(parsed.command, parsed.timeout_seconds, parsed.max_length, parsed.shell).await
+    }
+    
     pub async fn execute_command(
         &self,
         command: String,
@@ -210,70 +514,348 @@ impl SessionWindow {
         let start_time = std::time::Instant::now();
         let execution_time = chrono::Utc::now();
         
-        // Parse command modifiers
-        let timeout = timeout_seconds.unwrap_or(10); // Default 10 seconds like ScreenConnect
-        let max_len = max_length.unwrap_or(8192);     // Default 8KB
+        let timeout = timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let max_len = max_length.unwrap_or(DEFAULT_MAX_LENGTH);
         
         info!("Executing command: {} (timeout: {}s, max_length: {})", command, timeout, max_len);
         
-        // TODO: Implement actual command execution via WebSocket to remote machine
-        let output = format!("Command executed: {}\n(Implementation pending)", command);
-        let exit_code = Some(0);
-        
-        let duration = start_time.elapsed();
-        
-        let execution = CommandExecution {
-            id: Uuid::new_v4(),
+        let (program, args) = shell_invocation(&command, shell.as_deref());
+        let (program, args) = match &self.elevation {
+            Some(context) => context.wrap(&program, &args)?,
+            None => (program, args),
+        };
+        let limits = ExecutionLimits {
+            timeout: Some(std::time::Duration::from_secs(timeout as u64)),
+            max_output_bytes: max_len.saturating_add(OUTPUT_SLACK),
+        };
+        let mut handle = execution::spawn_tool(Path::new(&program), &args, None, limits)?;
+        let execution_id = handle.execution_id;
+        self.command_history.write().await.push(CommandExecution {
+            id: execution_id,
             command: command.clone(),
-            output,
-            exit_code,
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: None,
             execution_time,
-            duration_ms: duration.as_millis() as u64,
+            duration_ms: 0,
             timeout_seconds: timeout,
             max_length: max_len,
             shell,
-        };
+            executed_by: self.technician.clone(),
+            truncated: false,
+            timed_out: false,
+            cancelled: false,
+        });
         
-        self.command_history.write().await.push(execution.clone());
+        let status = self.stream_output(&mut handle, execution_id).await;
+        let execution = self.finish_execution(execution_id, start_time, status, Some(max_len)).await?;
+        self.store_command(&execution).await;
+        if let Some(recorder) = &self.recorder {
+            recorder.record_command(&command, execution.exit_code);
+        }
         
-        let event_details = HashMap::from([
+        let mut event_details = HashMap::from([
             ("command".to_string(), command),
+            ("command_id".to_string(), execution.id.to_string()),
             ("duration_ms".to_string(), execution.duration_ms.to_string()),
         ]);
+        if let Some(context) = &self.elevation {
+            event_details.insert("elevated".to_string(), context.level.to_string());
+        }
         
         self.add_timeline_event_with_details("command_executed", "Command executed", event_details).await;
         
         Ok(execution)
     }
     
-    pub async fn launch_tool(&self, tool_name: String, args: Vec<String>) -> Result<()> {
+    /// Keep a finished command in the session's history file and send it to
+    /// the server's timeline; neither failing loses the result on screen
+    async fn store_command(&self, execution: &CommandExecution) {
+        if let Err(e) = self.history.append(execution).await {
+            warn!("Failed to save command history to {}: {}", self.history.path().display(), e);
+        }
+        
+        let (server_url, session_id, token) = (
+            self.server_url.clone(),
+            self.session_info.session_id.clone(),
+            self.auth_token.clone(),
+        );
+        let execution = execution.clone();
+        tokio::spawn(async move {
+            if let Err(e) = commands::upload(&server_url, &session_id, &token, &execution).await {
+                warn!("Failed to sync command {} to the server: {}", execution.id, e);
+            }
+        });
+    }
+    
+    /// Append a run's output to its Commands tab entry as it arrives
+    async fn stream_output(&self, handle: &mut ToolExecutionHandle, execution_id: Uuid) -> ToolExitStatus {
+        let mut status = ToolExitStatus::Exited(None);
+        while let Some(event) = handle.next_event().await {
+            let mut history = self.command_history.write().await;
+            let Some(entry) = history.iter_mut().find(|entry| entry.id == execution_id) else {
+                continue;
+            };
+            match event {
+                ToolOutputEvent::Stdout(line) => {
+                    entry.stdout.push_str(&line);
+                    entry.stdout.push('\n');
+                }
+                ToolOutputEvent::Stderr(line) => {
+                    entry.stderr.push_str(&line);
+                    entry.stderr.push('\n');
+                }
+                ToolOutputEvent::Truncated => entry.truncated = true,
+                ToolOutputEvent::Finished(finished) => status = finished,
+            }
+        }
+        status
+    }
+    
+    /// Record how a run ended, cutting its output at `max_length` if given
+    async fn finish_execution(
+        &self,
+        execution_id: Uuid,
+        start_time: std::time::Instant,
+        status: ToolExitStatus,
+        max_length: Option<usize>,
+    ) -> Result<CommandExecution> {
+        let mut history = self.command_history.write().await;
+        let entry = history.iter_mut()
+            .find(|entry| entry.id == execution_id)
+            .ok_or_else(|| GhostLinkError::Other(format!("Command history entry {} disappeared", execution_id)))?;
+        entry.duration_ms = start_time.elapsed().as_millis() as u64;
+        if let Some(max_length) = max_length {
+            if commands::truncate_output(&mut entry.stdout, &mut entry.stderr, max_length) {
+                entry.truncated = true;
+            }
+        }
+        match status {
+            ToolExitStatus::Exited(code) => entry.exit_code = code,
+            ToolExitStatus::TimedOut => entry.timed_out = true,
+            ToolExitStatus::Cancelled => entry.cancelled = true,
+        }
+        Ok(entry.clone())
+    }
+    
+    /// Keep the sidebar's `tools` in step with the toolbox until it is dropped
+    fn watch_toolbox(toolbox: &Arc<ToolboxManager>, tools: Arc<RwLock<Vec<Tool>>>) {
+        let mut changes = toolbox.subscribe();
+        let toolbox = Arc::downgrade(toolbox);
+        tokio::spawn(async move {
+            while changes.changed().await.is_ok() {
+                let Some(toolbox) = toolbox.upgrade() else {
+                    break;
+                };
+                *tools.write().await = toolbox.list_tools();
+            }
+        });
+    }
+    
+    /// Run a toolbox tool, appending its output to the Commands tab as it arrives
+    pub async fn launch_tool(
+        &self,
+        tool_name: String,
+        vars: HashMap<String, String>,
+        args: Vec<String>,
+    ) -> Result<CommandExecution> {
         info!("Launching tool: {} with args: {:?}", tool_name, args);
         
-        let toolbox = self.toolbox.lock().await;
-        let tools = toolbox.list_tools();
+        let (mut handle, limits) = {
+            let toolbox = &self.toolbox;
+            let Some(tool_id) = toolbox.list_tools().iter().find(|t| t.name == tool_name).map(|t| t.id) else {
+                warn!("Tool not found: {}", tool_name);
+                return Err(ToolError::NotFound { tool: tool_name }.into());
+            };
+            let limits = toolbox.execution_limits();
+            // Admin tools run with the rights the server approved for this session
+            let handle = toolbox.execute_tool_streaming_with(&tool_id, &vars, args.clone(), limits, self.elevation.as_ref()).await?;
+            (handle, limits)
+        };
         
-        if let Some(tool) = tools.iter().find(|t| t.name == tool_name) {
-            // TODO: Launch tool on remote machine or locally depending on tool type
-            info!("Tool found: {} - {}", tool.name, tool.description);
-            
-            let event_details = HashMap::from([
-                ("tool_name".to_string(), tool_name.clone()),
-                ("args".to_string(), args.join(" ")),
-            ]);
-            
-            self.add_timeline_event_with_details("tool_launched", &format!("Tool launched: {}", tool_name), event_details).await;
-        } else {
-            warn!("Tool not found: {}", tool_name);
+        let start_time = std::time::Instant::now();
+        let command = format!("{} {}", tool_name, args.join(" ")).trim_end().to_string();
+        let execution_id = handle.execution_id;
+        self.command_history.write().await.push(CommandExecution {
+            id: execution_id,
+            command: command.clone(),
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: None,
+            execution_time: chrono::Utc::now(),
+            duration_ms: 0,
+            timeout_seconds: limits.timeout.map(|t| t.as_secs() as u32).unwrap_or(0),
+            max_length: limits.max_output_bytes,
+            shell: None,
+            executed_by: self.technician.clone(),
+            truncated: false,
+            timed_out: false,
+            cancelled: false,
+        });
+        
+        let status = self.stream_output(&mut handle, execution_id).await;
+        let execution = self.finish_execution(execution_id, start_time, status, None).await?;
+        self.store_command(&execution).await;
+        if let Some(recorder) = &self.recorder {
+            recorder.record_command(&command, execution.exit_code);
+        }
+        
+        let event_details = HashMap::from([
+            ("tool_name".to_string(), tool_name.clone()),
+            ("command_id".to_string(), execution.id.to_string()),
+            ("args".to_string(), args.join(" ")),
+            ("duration_ms".to_string(), execution.duration_ms.to_string()),
+        ]);
+        
+        self.add_timeline_event_with_details("tool_launched", &format!("Tool launched: {}", tool_name), event_details).await;
+        
+        Ok(execution)
+    }
+    
+    fn registry_roots() -> Vec<RegistryTreeNode> {
+        [
+            RegistryHive::ClassesRoot,
+            RegistryHive::CurrentUser,
+            RegistryHive::LocalMachine,
+            RegistryHive::Users,
+            RegistryHive::CurrentConfig,
+        ]
+        .iter()
+        .map(|hive| RegistryTreeNode::new(hive.name().to_string(), hive.name().to_string()))
+        .collect()
+    }
+    
+    /// Load a key's values and subkeys from the agent and expand it in the registry tree
+    pub async fn expand_registry_key(&self, path: &str) -> Result<()> {
+        let result = self.registry_api(reqwest::Method::GET, "key", &[("path", path)], None).await?;
+        let info: RegistryKeyInfo = serde_json::from_value(result)?;
+        
+        let mut tree = self.registry_tree.write().await;
+        let node = tree.iter_mut()
+            .find_map(|root| root.find_mut(&info.path))
+            .ok_or_else(|| GhostLinkError::Other(format!("Registry key {} is not in the tree", info.path)))?;
+        
+        // Keep already loaded children so expanded branches survive a refresh
+        let mut previous = std::mem::take(&mut node.children);
+        node.children = info.subkeys.iter()
+            .map(|name| {
+                let child_path = format!("{}\\{}", info.path, name);
+                match previous.iter().position(|c| c.path.eq_ignore_ascii_case(&child_path)) {
+                    Some(index) => previous.swap_remove(index),
+                    None => RegistryTreeNode::new(child_path, name.clone()),
+                }
+            })
+            .collect();
+        node.values = info.values;
+        node.expanded = true;
+        
+        Ok(())
+    }
+    
+    pub async fn collapse_registry_key(&self, path: &str) {
+        let mut tree = self.registry_tree.write().await;
+        if let Some(node) = tree.iter_mut().find_map(|root| root.find_mut(path)) {
+            node.expanded = false;
         }
+    }
+    
+    pub async fn set_registry_value(&self, path: &str, name: &str, value: RegistryData) -> Result<()> {
+        let body = serde_json::json!({ "path": path, "name": name, "value": value });
+        self.registry_api(reqwest::Method::PUT, "value", &[], Some(body)).await?;
+        self.record_registry_change("registry_value_set", "Registry value set", path, Some(name)).await;
+        self.expand_registry_key(path).await
+    }
+    
+    pub async fn delete_registry_value(&self, path: &str, name: &str) -> Result<()> {
+        self.registry_api(reqwest::Method::DELETE, "value", &[("path", path), ("name", name)], None).await?;
+        self.record_registry_change("registry_value_deleted", "Registry value deleted", path, Some(name)).await;
+        self.expand_registry_key(path).await
+    }
+    
+    pub async fn create_registry_key(&self, parent: &str, name: &str) -> Result<()> {
+        let path = format!("{}\\{}", parent, name);
+        let body = serde_json::json!({ "path": path });
+        self.registry_api(reqwest::Method::POST, "key", &[], Some(body)).await?;
+        self.record_registry_change("registry_key_created", "Registry key created", &path, None).await;
+        self.expand_registry_key(parent).await
+    }
+    
+    pub async fn delete_registry_key(&self, path: &str) -> Result<()> {
+        self.registry_api(reqwest::Method::DELETE, "key", &[("path", path)], None).await?;
+        self.record_registry_change("registry_key_deleted", "Registry key deleted", path, None).await;
         
+        if let Some((parent, _)) = path.rsplit_once('\\') {
+            self.expand_registry_key(parent).await?;
+        }
         Ok(())
     }
     
+    /// Export a key and its subkeys as .reg text
+    pub async fn export_registry_key(&self, path: &str) -> Result<String> {
+        let url = format!("{}/api/sessions/{}/registry/export", self.server_url, self.session_info.session_id);
+        let response = http_client()
+            .get(url)
+            .bearer_auth(&self.auth_token)
+            .query(&[("path", path)])
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let error: serde_json::Value = response.json().await.unwrap_or_default();
+            return Err(GhostLinkError::Other(format!("Registry export failed: {}", error["error"].as_str().unwrap_or("unknown error"))));
+        }
+        
+        Ok(response.text().await?)
+    }
+    
+    async fn registry_api(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        query: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        let url = format!("{}/api/sessions/{}/registry/{}", self.server_url, self.session_info.session_id, endpoint);
+        let mut request = http_client()
+            .request(method, url)
+            .bearer_auth(&self.auth_token)
+            .query(query);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        
+        let response = request.send().await?;
+        let status = response.status();
+        let payload: serde_json::Value = response.json().await.unwrap_or_default();
+        
+        if !status.is_success() {
+            return Err(GhostLinkError::Other(format!("Registry request failed ({}): {}", status, payload["error"].as_str().unwrap_or("unknown error"))));
+        }
+        
+        Ok(payload)
+    }
+    
+    async fn record_registry_change(&self, event_type: &str, description: &str, path: &str, value_name: Option<&str>) {
+        let mut details = HashMap::from([("path".to_string(), path.to_string())]);
+        if let Some(name) = value_name {
+            details.insert("value_name".to_string(), name.to_string());
+        }
+        self.add_timeline_event_with_details(event_type, description, details).await;
+    }
+    
     async fn add_timeline_event(&self, event_type: &str, description: &str) {
         self.add_timeline_event_with_details(event_type, description, HashMap::new()).await;
     }
     
     async fn add_timeline_event_with_details(&self, event_type: &str, description: &str, details: HashMap<String, String>) {
+        self.record_timeline_event(event_type, description, details, &self.technician).await;
+    }
+    
+    /// Show an event on the local timeline and send it to the server's
+    async fn record_timeline_event(&self, event_type: &str, description: &str, details: HashMap<String, String>, actor: &str) {
+        if let Some(events) = &self.events {
+            events.emit(event_kind(event_type), actor, event_details(event_type, description, &details));
+        }
         let event = TimelineEvent {
             id: Uuid::new_v4(),
             event_type: event_type.to_string(),
@@ -285,13 +867,68 @@ impl SessionWindow {
         self.timeline.write().await.push(event);
     }
     
+    /// Follow transfer progress of this session for the file transfer button
+    pub fn track_file_transfers(&self, mut progress: broadcast::Receiver<TransferProgress>) {
+        let session_id = self.session_info.session_id.clone();
+        let transfers = Arc::clone(&self.file_transfers);
+        let timeline = Arc::clone(&self.timeline);
+        let events = self.events.clone();
+        let technician = self.technician.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let update = match progress.recv().await {
+                    Ok(update) => update,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if update.session_id != session_id {
+                    continue;
+                }
+
+                let outcome = match &update.state {
+                    TransferState::Completed => Some(("file_transfer_completed", "File transferred".to_string())),
+                    TransferState::Failed(error) => Some(("file_transfer_failed", format!("File transfer failed: {}", error))),
+                    TransferState::Cancelled => Some(("file_transfer_cancelled", "File transfer cancelled".to_string())),
+                    TransferState::Active | TransferState::Interrupted => None,
+                };
+                if let Some((event_type, description)) = outcome {
+                    let details = HashMap::from([
+                        ("filename".to_string(), update.filename.clone()),
+                        ("bytes".to_string(), update.bytes_done.to_string()),
+                    ]);
+                    if let Some(events) = &events {
+                        events.emit(SessionEventKind::FileTransfer, &technician, event_details(event_type, &description, &details));
+                    }
+                    timeline.write().await.push(TimelineEvent {
+                        id: Uuid::new_v4(),
+                        event_type: event_type.to_string(),
+                        description,
+                        timestamp: chrono::Utc::now(),
+                        details,
+                    });
+                }
+
+                transfers.write().await.insert(update.transfer_id.clone(), update);
+            }
+        });
+    }
+
+    /// Latest progress of every transfer in this session
+    pub async fn file_transfer_progress(&self) -> Vec<TransferProgress> {
+        self.file_transfers.read().await.values().cloned().collect()
+    }
+    
     pub async fn get_session_summary(&self) -> HashMap<String, String> {
         let messages_count = self.messages.read().await.len();
         let notes_count = self.notes.read().await.len();
-        let commands_count = self.command_history.read().await.len();
+        let (commands_count, last_command_at) = {
+            let history = self.command_history.read().await;
+            (history.len(), history.iter().map(|c| c.execution_time).max())
+        };
         let events_count = self.timeline.read().await.len();
         
-        HashMap::from([
+        let mut summary = HashMap::from([
             ("session_id".to_string(), self.session_info.session_id.clone()),
             ("device_name".to_string(), self.session_info.device_name.clone()),
             ("operating_system".to_string(), self.session_info.operating_system.clone()),
@@ -299,10 +936,81 @@ impl SessionWindow {
             ("notes_count".to_string(), notes_count.to_string()),
             ("commands_count".to_string(), commands_count.to_string()),
             ("events_count".to_string(), events_count.to_string()),
-            ("backstage_mode".to_string(), self.is_backstage_mode.to_string()),
-            ("input_suspended".to_string(), self.input_suspended.to_string()),
-            ("screen_blanked".to_string(), self.screen_blanked.to_string()),
+            ("backstage_mode".to_string(), self.is_backstage_mode().to_string()),
+            ("input_suspended".to_string(), self.input_suspended().to_string()),
+            ("screen_blanked".to_string(), self.screen_blanked().to_string()),
             ("recording".to_string(), self.is_recording.to_string()),
-        ])
+        ]);
+        if let Some(at) = last_command_at {
+            summary.insert("last_command_at".to_string(), at.to_rfc3339());
+        }
+        summary
+    }
+    
+    /// Copy of the window's state for a UI to draw from
+    pub async fn snapshot(&self) -> WindowSnapshot {
+        WindowSnapshot {
+            session_info: self.session_info.clone(),
+            backstage_mode: self.is_backstage_mode(),
+            input_suspended: self.input_suspended(),
+            screen_blanked: self.screen_blanked(),
+            recording: self.is_recording,
+            tools: self.toolbox_tools.read().await.clone(),
+            messages: self.messages.read().await.clone(),
+            notes: self.notes.read().await.clone(),
+            note_templates: self.note_templates.read().await.clone(),
+            ticket_ref: self.ticket_ref.read().await.clone(),
+            closeout_required: self.closeout_required.load(Ordering::Relaxed),
+            ended: self.ended.load(Ordering::Relaxed),
+            timeline: self.timeline.read().await.clone(),
+            commands: self.command_history.read().await.clone(),
+            transfers: self.file_transfer_progress().await,
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Server timeline kind of a local timeline event
+fn event_kind(event_type: &str) -> SessionEventKind {
+    match event_type {
+        "note_added" | "closeout_note_added" => SessionEventKind::Note,
+        "message_sent" => SessionEventKind::Message,
+        "command_executed" => SessionEventKind::Command,
+        "tool_launched" => SessionEventKind::ToolLaunch,
+        "backstage_enabled" | "backstage_disabled" => SessionEventKind::BackstageToggle,
+        event_type if event_type.starts_with("file_transfer_") => SessionEventKind::FileTransfer,
+        _ => SessionEventKind::Other,
+    }
+}
+
+fn event_details(event_type: &str, description: &str, details: &HashMap<String, String>) -> serde_json::Value {
+    let mut fields: serde_json::Map<String, serde_json::Value> = details
+        .iter()
+        .map(|(key, value)| (key.clone(), serde_json::Value::String(value.clone())))
+        .collect();
+    fields.insert("event_type".to_string(), event_type.into());
+    fields.insert("description".to_string(), description.into());
+    serde_json::Value::Object(fields)
+}
+
+/// Program and arguments that run `command` through `shell`, or through the
+/// platform's default shell
+fn shell_invocation(command: &str, shell: Option<&str>) -> (String, Vec<String>) {
+    let shell = shell.unwrap_or(if cfg!(windows) { "cmd" } else { "sh" });
+    let flag = match shell.to_lowercase().trim_end_matches(".exe") {
+        "cmd" => "/C",
+        "powershell" | "pwsh" => "-Command",
+        _ => "-c",
+    };
+    (shell.to_string(), vec![flag.to_string(), command.to_string()])
+}
+
+/// The signed-in technician's email from the server's JWT; the server checks
+/// the signature, this only labels the history
+fn token_email(token: &str) -> Option<String> {
+    use base64::Engine;
+    
+    let payload = token.split('.').nth(1)?;
+    let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&claims).ok()?;
+    claims["email"].as_str().filter(|email| !email.is_empty()).map(str::to_string)
+}
@@ -0,0 +1,591 @@
+//! What the desktop session window keeps between frames, and the bridge it
+//! talks to `SessionWindow` through.
+//!
+//! The UI never calls `SessionWindow` itself: user actions become
+//! `WindowRequest`s that the bridge runs on the tokio runtime, each in its
+//! own task so a long command does not hold up the control buttons, and the
+//! UI draws from the latest `WindowSnapshot`. Keeping the drafts, the open
+//! tab and the in-flight count here leaves the drawing code free of state
+//! and lets the transitions be tested without a display.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+use crate::error::Result;
+
+use super::notes::NoteTemplate;
+use super::window::{SessionNote, SessionTab, SessionWindow, WindowSnapshot};
+
+/// How often the bridge copies the window's state while nothing happens,
+/// which is also how soon streamed command output shows up
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Label of `WindowRequest::EndSession`
+const END_SESSION: &str = "Ending the session";
+
+/// A switch on the window's control bar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionControl {
+    Backstage,
+    SuspendInput,
+    BlankScreen,
+}
+
+impl SessionControl {
+    pub fn label(self) -> &'static str {
+        match self {
+            SessionControl::Backstage => "Backstage",
+            SessionControl::SuspendInput => "Suspend input",
+            SessionControl::BlankScreen => "Blank screen",
+        }
+    }
+
+    /// Whether the switch is on in `snapshot`
+    pub fn is_on(self, snapshot: &WindowSnapshot) -> bool {
+        match self {
+            SessionControl::Backstage => snapshot.backstage_mode,
+            SessionControl::SuspendInput => snapshot.input_suspended,
+            SessionControl::BlankScreen => snapshot.screen_blanked,
+        }
+    }
+}
+
+/// Something the user asked the session window to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowRequest {
+    SwitchTab(SessionTab),
+    /// Commands tab text, modifiers included
+    RunCommand(String),
+    LaunchTool(String),
+    SendMessage(String),
+    AddNote { content: String, private: bool, template: Option<Uuid> },
+    EditNote { id: Uuid, content: String },
+    /// Blank unlinks the ticket
+    SetTicket(String),
+    SetControl(SessionControl, bool),
+    EndSession,
+    /// The close-out note and then the end, in one request so the end
+    /// can't overtake the note
+    CloseOut { content: String, template: Option<Uuid> },
+}
+
+impl WindowRequest {
+    /// What the request is called when it fails
+    pub fn label(&self) -> &'static str {
+        match self {
+            WindowRequest::SwitchTab(_) => "Switching tabs",
+            WindowRequest::RunCommand(_) => "Command",
+            WindowRequest::LaunchTool(_) => "Tool",
+            WindowRequest::SendMessage(_) => "Message",
+            WindowRequest::AddNote { .. } => "Note",
+            WindowRequest::EditNote { .. } => "Editing the note",
+            WindowRequest::SetTicket(_) => "Linking the ticket",
+            WindowRequest::SetControl(control, _) => control.label(),
+            WindowRequest::EndSession => END_SESSION,
+            WindowRequest::CloseOut { .. } => "Close-out note",
+        }
+    }
+}
+
+/// How a request the bridge ran turned out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowOutcome {
+    pub label: &'static str,
+    pub result: std::result::Result<(), String>,
+}
+
+/// What outlives the window: the tab it was left on, whether the toolbox
+/// was open and whether system shortcuts go to the remote machine. Its size
+/// is kept by the windowing layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowState {
+    pub last_tab: SessionTab,
+    pub show_toolbox: bool,
+    pub send_shortcuts: bool,
+}
+
+impl Default for WindowState {
+    fn default() -> Self {
+        Self {
+            last_tab: SessionTab::Start,
+            show_toolbox: true,
+            send_shortcuts: false,
+        }
+    }
+}
+
+/// The window's own state between frames
+#[derive(Debug, Clone)]
+pub struct WindowView {
+    pub tab: SessionTab,
+    pub show_toolbox: bool,
+    pub show_transfers: bool,
+    /// Alt+Tab, the Windows key and the like go to the remote machine
+    pub send_shortcuts: bool,
+    pub command_input: String,
+    pub message_draft: String,
+    pub note_draft: String,
+    pub note_private: bool,
+    /// Template the note or close-out draft was started from
+    pub note_template: Option<Uuid>,
+    /// Note being edited and its new text
+    pub editing: Option<(Uuid, String)>,
+    pub ticket_draft: String,
+    pub closeout_draft: String,
+    /// The technician closed the window and the session is being ended
+    pub closing: bool,
+    pub selected_tool: Option<String>,
+    /// Last failure, shown until dismissed or replaced
+    pub status: Option<String>,
+    /// Ending the session failed, so closing again leaves it to the server
+    end_failed: bool,
+    in_flight: usize,
+}
+
+impl WindowView {
+    pub fn new(state: WindowState) -> Self {
+        Self {
+            tab: state.last_tab,
+            show_toolbox: state.show_toolbox,
+            show_transfers: false,
+            send_shortcuts: state.send_shortcuts,
+            command_input: String::new(),
+            message_draft: String::new(),
+            note_draft: String::new(),
+            note_private: false,
+            note_template: None,
+            editing: None,
+            ticket_draft: String::new(),
+            closeout_draft: String::new(),
+            closing: false,
+            selected_tool: None,
+            status: None,
+            end_failed: false,
+            in_flight: 0,
+        }
+    }
+
+    pub fn state(&self) -> WindowState {
+        WindowState {
+            last_tab: self.tab,
+            show_toolbox: self.show_toolbox,
+            send_shortcuts: self.send_shortcuts,
+        }
+    }
+
+    /// Whether requests are still running
+    pub fn is_busy(&self) -> bool {
+        self.in_flight > 0
+    }
+
+    pub fn select_tab(&mut self, tab: SessionTab) -> Option<WindowRequest> {
+        if tab == self.tab {
+            return None;
+        }
+        self.tab = tab;
+        Some(self.start(WindowRequest::SwitchTab(tab)))
+    }
+
+    /// Run the Commands tab's input; blank input runs nothing
+    pub fn submit_command(&mut self) -> Option<WindowRequest> {
+        if self.command_input.trim().is_empty() {
+            return None;
+        }
+        let text = std::mem::take(&mut self.command_input);
+        Some(self.start(WindowRequest::RunCommand(text)))
+    }
+
+    pub fn submit_message(&mut self) -> Option<WindowRequest> {
+        let message = std::mem::take(&mut self.message_draft).trim().to_string();
+        if message.is_empty() {
+            return None;
+        }
+        Some(self.start(WindowRequest::SendMessage(message)))
+    }
+
+    pub fn submit_note(&mut self) -> Option<WindowRequest> {
+        let content = std::mem::take(&mut self.note_draft).trim().to_string();
+        if content.is_empty() {
+            return None;
+        }
+        let template = self.note_template.take();
+        Some(self.start(WindowRequest::AddNote { content, private: self.note_private, template }))
+    }
+
+    /// Start the note draft from `template`, or add it to what is there
+    pub fn apply_template(&mut self, template: &NoteTemplate) {
+        self.note_template = Some(template.id);
+        fill_draft(&mut self.note_draft, &template.body);
+    }
+
+    /// Start the close-out draft from `template`
+    pub fn apply_closeout_template(&mut self, template: &NoteTemplate) {
+        self.note_template = Some(template.id);
+        fill_draft(&mut self.closeout_draft, &template.body);
+    }
+
+    pub fn start_edit(&mut self, note: &SessionNote) {
+        self.editing = Some((note.id, note.content.clone()));
+    }
+
+    /// Save the note being edited; blank text keeps the note as it was
+    pub fn submit_edit(&mut self) -> Option<WindowRequest> {
+        let (id, content) = self.editing.take()?;
+        let content = content.trim().to_string();
+        if content.is_empty() {
+            return None;
+        }
+        Some(self.start(WindowRequest::EditNote { id, content }))
+    }
+
+    pub fn submit_ticket(&mut self) -> WindowRequest {
+        let ticket = self.ticket_draft.trim().to_string();
+        self.start(WindowRequest::SetTicket(ticket))
+    }
+
+    /// Whether the window may close now: the session has ended, or ending
+    /// it failed and the technician closes again anyway
+    pub fn may_close(&self, snapshot: &WindowSnapshot) -> bool {
+        snapshot.ended || self.end_failed
+    }
+
+    /// The technician closed the window; end the session first, unless
+    /// that is already under way
+    pub fn request_close(&mut self) -> Option<WindowRequest> {
+        if self.closing {
+            return None;
+        }
+        self.closing = true;
+        Some(self.start(WindowRequest::EndSession))
+    }
+
+    /// Back out of closing from the close-out prompt
+    pub fn cancel_close(&mut self) {
+        self.closing = false;
+    }
+
+    /// Send the close-out note, which ends the session once stored
+    pub fn submit_closeout(&mut self) -> Option<WindowRequest> {
+        let content = self.closeout_draft.trim().to_string();
+        if content.is_empty() {
+            return None;
+        }
+        self.closeout_draft.clear();
+        let template = self.note_template.take();
+        Some(self.start(WindowRequest::CloseOut { content, template }))
+    }
+
+    /// Launch a toolbox tool; its output lands on the Commands tab, so the
+    /// window goes there
+    pub fn launch_tool(&mut self, name: &str) -> Vec<WindowRequest> {
+        self.selected_tool = Some(name.to_string());
+        let mut requests: Vec<WindowRequest> = self.select_tab(SessionTab::Commands).into_iter().collect();
+        requests.push(self.start(WindowRequest::LaunchTool(name.to_string())));
+        requests
+    }
+
+    /// Flip a control bar switch from the state `snapshot` shows
+    pub fn toggle(&mut self, control: SessionControl, snapshot: &WindowSnapshot) -> WindowRequest {
+        self.start(WindowRequest::SetControl(control, !control.is_on(snapshot)))
+    }
+
+    /// Take in a finished request
+    pub fn apply(&mut self, outcome: WindowOutcome) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if outcome.label == END_SESSION && outcome.result.is_err() {
+            self.closing = false;
+            self.end_failed = true;
+        }
+        if let Err(e) = outcome.result {
+            self.status = Some(format!("{} failed: {}", outcome.label, e));
+        }
+    }
+
+    pub fn dismiss_status(&mut self) {
+        self.status = None;
+    }
+
+    fn start(&mut self, request: WindowRequest) -> WindowRequest {
+        self.in_flight += 1;
+        request
+    }
+}
+
+fn fill_draft(draft: &mut String, body: &str) {
+    if draft.trim().is_empty() {
+        *draft = body.to_string();
+    } else {
+        draft.push('\n');
+        draft.push_str(body);
+    }
+}
+
+/// The UI's side of the bridge to a running `SessionWindow`
+pub struct WindowBridge {
+    requests: mpsc::UnboundedSender<WindowRequest>,
+    outcomes: mpsc::UnboundedReceiver<WindowOutcome>,
+    snapshots: watch::Receiver<WindowSnapshot>,
+}
+
+impl WindowBridge {
+    /// Serve `window` on the current runtime until the bridge is dropped
+    pub async fn spawn(window: Arc<SessionWindow>) -> Self {
+        let (requests, mut request_rx) = mpsc::unbounded_channel::<WindowRequest>();
+        let (outcome_tx, outcomes) = mpsc::unbounded_channel();
+        let (snapshot_tx, snapshots) = watch::channel(window.snapshot().await);
+
+        tokio::spawn(async move {
+            let mut refresh = tokio::time::interval(SNAPSHOT_INTERVAL);
+            loop {
+                tokio::select! {
+                    request = request_rx.recv() => {
+                        let Some(request) = request else { break };
+                        let window = Arc::clone(&window);
+                        let outcome_tx = outcome_tx.clone();
+                        tokio::spawn(async move {
+                            let label = request.label();
+                            let result = perform(&window, request).await.map_err(|e| e.to_string());
+                            let _ = outcome_tx.send(WindowOutcome { label, result });
+                        });
+                    }
+                    _ = refresh.tick() => {}
+                }
+                if snapshot_tx.send(window.snapshot().await).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { requests, outcomes, snapshots }
+    }
+
+    pub fn send(&self, request: WindowRequest) {
+        // The bridge only stops once this side is dropped
+        let _ = self.requests.send(request);
+    }
+
+    /// The next finished request, if any
+    pub fn try_outcome(&mut self) -> Option<WindowOutcome> {
+        self.outcomes.try_recv().ok()
+    }
+
+    /// The window's state as of the last refresh
+    pub fn snapshot(&self) -> WindowSnapshot {
+        self.snapshots.borrow().clone()
+    }
+}
+
+async fn perform(window: &SessionWindow, request: WindowRequest) -> Result<()> {
+    match request {
+        WindowRequest::SwitchTab(tab) => window.switch_tab(tab).await,
+        WindowRequest::RunCommand(text) => {
+            window.run_command(&text).await?;
+        }
+        WindowRequest::LaunchTool(name) => {
+            window.launch_tool(name, Default::default(), Vec::new()).await?;
+        }
+        WindowRequest::SendMessage(message) => window.send_message(message, true).await?,
+        WindowRequest::AddNote { content, private, template } => {
+            window.add_note(content, window.technician().to_string(), private, template).await?;
+        }
+        WindowRequest::EditNote { id, content } => window.edit_note(id, content).await?,
+        WindowRequest::SetTicket(ticket) => window.set_ticket_ref(&ticket).await?,
+        WindowRequest::EndSession => {
+            window.end_session().await?;
+        }
+        WindowRequest::CloseOut { content, template } => {
+            window.close_out(content, template).await?;
+        }
+        WindowRequest::SetControl(SessionControl::Backstage, true) => window.enable_backstage_mode().await?,
+        WindowRequest::SetControl(SessionControl::Backstage, false) => window.disable_backstage_mode().await?,
+        WindowRequest::SetControl(SessionControl::SuspendInput, true) => window.suspend_input().await?,
+        WindowRequest::SetControl(SessionControl::SuspendInput, false) => window.resume_input().await?,
+        WindowRequest::SetControl(SessionControl::BlankScreen, true) => window.blank_screen().await?,
+        WindowRequest::SetControl(SessionControl::BlankScreen, false) => window.unblank_screen().await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::window::SessionInfo;
+
+    fn snapshot() -> WindowSnapshot {
+        WindowSnapshot {
+            session_info: SessionInfo {
+                session_id: "session-1".to_string(),
+                device_name: "Unknown".to_string(),
+                operating_system: "Unknown".to_string(),
+                ip_address: "0.0.0.0".to_string(),
+                user_name: None,
+                connected_time: chrono::Utc::now(),
+                last_activity: chrono::Utc::now(),
+            },
+            backstage_mode: false,
+            input_suspended: true,
+            screen_blanked: false,
+            recording: false,
+            tools: Vec::new(),
+            messages: Vec::new(),
+            notes: Vec::new(),
+            note_templates: Vec::new(),
+            ticket_ref: None,
+            closeout_required: false,
+            ended: false,
+            timeline: Vec::new(),
+            commands: Vec::new(),
+            transfers: Vec::new(),
+        }
+    }
+
+    fn done(label: &'static str) -> WindowOutcome {
+        WindowOutcome { label, result: Ok(()) }
+    }
+
+    #[test]
+    fn test_window_reopens_on_the_tab_it_was_left_on() {
+        let mut view = WindowView::new(WindowState::default());
+        assert_eq!(view.tab, SessionTab::Start);
+        assert_eq!(view.select_tab(SessionTab::Start), None);
+        assert_eq!(view.select_tab(SessionTab::Notes), Some(WindowRequest::SwitchTab(SessionTab::Notes)));
+
+        let saved = serde_json::to_string(&view.state()).unwrap();
+        let restored = WindowView::new(serde_json::from_str(&saved).unwrap());
+        assert_eq!(restored.tab, SessionTab::Notes);
+        assert!(restored.show_toolbox);
+        assert!(!restored.send_shortcuts);
+
+        // State saved before a field existed still loads
+        let older: WindowState = serde_json::from_str(r#"{"last_tab":"Commands"}"#).unwrap();
+        assert_eq!(older, WindowState { last_tab: SessionTab::Commands, show_toolbox: true, send_shortcuts: false });
+    }
+
+    #[test]
+    fn test_commands_are_sent_once_and_failures_reported() {
+        let mut view = WindowView::new(WindowState::default());
+        view.command_input = "  \n".to_string();
+        assert_eq!(view.submit_command(), None);
+        assert!(!view.is_busy());
+
+        view.command_input = "#timeout 5\nuptime".to_string();
+        assert_eq!(view.submit_command(), Some(WindowRequest::RunCommand("#timeout 5\nuptime".to_string())));
+        assert!(view.command_input.is_empty());
+        assert!(view.is_busy());
+
+        view.apply(WindowOutcome { label: "Command", result: Err("Unknown modifier #foo".to_string()) });
+        assert!(!view.is_busy());
+        assert_eq!(view.status.as_deref(), Some("Command failed: Unknown modifier #foo"));
+        view.dismiss_status();
+        assert_eq!(view.status, None);
+    }
+
+    #[test]
+    fn test_drafts_are_trimmed_and_cleared() {
+        let mut view = WindowView::new(WindowState::default());
+        view.message_draft = "   ".to_string();
+        assert_eq!(view.submit_message(), None);
+
+        view.message_draft = " Rebooting now \n".to_string();
+        assert_eq!(view.submit_message(), Some(WindowRequest::SendMessage("Rebooting now".to_string())));
+        assert!(view.message_draft.is_empty());
+
+        view.note_draft = "Printer driver reinstalled".to_string();
+        view.note_private = true;
+        assert_eq!(view.submit_note(), Some(WindowRequest::AddNote {
+            content: "Printer driver reinstalled".to_string(),
+            private: true,
+            template: None,
+        }));
+        assert!(view.note_draft.is_empty());
+
+        view.apply(done("Message"));
+        view.apply(done("Note"));
+        assert!(!view.is_busy());
+        assert_eq!(view.status, None);
+    }
+
+    #[test]
+    fn test_tool_launch_opens_the_commands_tab() {
+        let mut view = WindowView::new(WindowState::default());
+        assert_eq!(view.launch_tool("Disk Cleanup"), vec![
+            WindowRequest::SwitchTab(SessionTab::Commands),
+            WindowRequest::LaunchTool("Disk Cleanup".to_string()),
+        ]);
+        assert_eq!(view.selected_tool.as_deref(), Some("Disk Cleanup"));
+
+        // Already there, so only the launch goes out
+        assert_eq!(view.launch_tool("Disk Cleanup"), vec![WindowRequest::LaunchTool("Disk Cleanup".to_string())]);
+    }
+
+    #[test]
+    fn test_controls_flip_what_the_session_shows() {
+        let mut view = WindowView::new(WindowState::default());
+        let snapshot = snapshot();
+        assert_eq!(
+            view.toggle(SessionControl::Backstage, &snapshot),
+            WindowRequest::SetControl(SessionControl::Backstage, true)
+        );
+        assert_eq!(
+            view.toggle(SessionControl::SuspendInput, &snapshot),
+            WindowRequest::SetControl(SessionControl::SuspendInput, false)
+        );
+
+        view.apply(WindowOutcome { label: SessionControl::Backstage.label(), result: Err("Not connected".to_string()) });
+        assert!(view.is_busy());
+        assert_eq!(view.status.as_deref(), Some("Backstage failed: Not connected"));
+    }
+
+    #[test]
+    fn test_templates_start_the_draft() {
+        let mut view = WindowView::new(WindowState::default());
+        let template = NoteTemplate { id: Uuid::new_v4(), name: "Printer fix".to_string(), body: "## Cause".to_string() };
+        view.apply_template(&template);
+        assert_eq!(view.note_draft, "## Cause");
+        view.note_draft.push_str("\nSpooler hung");
+        assert_eq!(view.submit_note(), Some(WindowRequest::AddNote {
+            content: "## Cause\nSpooler hung".to_string(),
+            private: false,
+            template: Some(template.id),
+        }));
+        assert_eq!(view.note_template, None);
+
+        // A draft already written keeps its text
+        view.note_draft = "Called the user".to_string();
+        view.apply_template(&template);
+        assert_eq!(view.note_draft, "Called the user\n## Cause");
+    }
+
+    #[test]
+    fn test_closing_ends_the_session_first() {
+        let mut view = WindowView::new(WindowState::default());
+        let mut snapshot = snapshot();
+        assert!(!view.may_close(&snapshot));
+        assert_eq!(view.request_close(), Some(WindowRequest::EndSession));
+        assert_eq!(view.request_close(), None);
+
+        // The policy wants a close-out note, which the prompt sends
+        view.apply(done(END_SESSION));
+        snapshot.closeout_required = true;
+        assert!(view.closing && !view.may_close(&snapshot));
+        assert_eq!(view.submit_closeout(), None);
+        view.closeout_draft = " Printing works again ".to_string();
+        assert_eq!(view.submit_closeout(), Some(WindowRequest::CloseOut {
+            content: "Printing works again".to_string(),
+            template: None,
+        }));
+        snapshot.ended = true;
+        assert!(view.may_close(&snapshot));
+
+        // A server that can't be reached doesn't keep the window open
+        let mut view = WindowView::new(WindowState::default());
+        view.request_close();
+        view.apply(WindowOutcome { label: END_SESSION, result: Err("connection refused".to_string()) });
+        assert!(!view.closing);
+        assert!(view.may_close(&self::snapshot()));
+    }
+}
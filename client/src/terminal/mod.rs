@@ -0,0 +1,615 @@
+//! Remote terminals on a real pseudo-terminal
+//!
+//! The technician opens a terminal with `TerminalOpen`; the agent starts an
+//! allowed shell on a PTY (ConPTY on Windows) and streams everything it
+//! prints back as `TerminalOutput`, which travels as a binary envelope once
+//! the relay speaks it. `TerminalInput` is typed into the terminal and
+//! `TerminalResize` changes its size. The terminal closes when the shell
+//! exits, the technician sends `TerminalClose`, the session ends or it sits
+//! idle too long; `TerminalClosed` reports why.
+//!
+//! A terminal is keyed by the session it was opened for. Recent output is
+//! kept per terminal so the server can show it again through
+//! `TerminalScrollbackRequest`.
+
+#![allow(dead_code)]
+
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::connection::RelayMessage;
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use unix::Pty;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use windows::Pty;
+
+/// Largest read from a terminal sent as one `TerminalOutput`
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Policy for terminals the technician opens on this machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerminalPolicy {
+    pub enabled: bool,
+    /// Shells a terminal may run, as full paths. A request naming a bare
+    /// shell such as `bash` gets the allowed shell with that file name.
+    pub allowed_shells: Vec<String>,
+    /// Shell for requests that don't name one; unset uses `$SHELL` or
+    /// `%COMSPEC%` when allowed, otherwise the first allowed shell
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_shell: Option<String>,
+    /// Where shells start when the request doesn't say; unset is the home directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_cwd: Option<PathBuf>,
+    /// Recent output kept per terminal, in bytes
+    pub scrollback_bytes: usize,
+    /// Seconds without input or output before a terminal is closed; 0 never closes it
+    pub idle_timeout_secs: u64,
+    /// Terminals open at once across all sessions
+    pub max_terminals: usize,
+}
+
+impl Default for TerminalPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allowed_shells: default_shells(),
+            default_shell: None,
+            default_cwd: None,
+            scrollback_bytes: 256 * 1024,
+            idle_timeout_secs: 30 * 60,
+            max_terminals: 8,
+        }
+    }
+}
+
+#[cfg(windows)]
+fn default_shells() -> Vec<String> {
+    [
+        r"C:\Windows\System32\cmd.exe",
+        r"C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe",
+        r"C:\Program Files\PowerShell\7\pwsh.exe",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+#[cfg(not(windows))]
+fn default_shells() -> Vec<String> {
+    ["/bin/sh", "/bin/bash", "/usr/bin/bash", "/bin/zsh", "/usr/bin/zsh", "/usr/bin/fish"]
+        .map(String::from)
+        .to_vec()
+}
+
+impl TerminalPolicy {
+    /// The allowed shell for a request, or the default one
+    pub fn resolve_shell(&self, requested: Option<&str>) -> Result<String> {
+        if !self.enabled {
+            bail!("Remote terminals are disabled by policy");
+        }
+
+        let Some(requested) = requested.map(str::trim).filter(|shell| !shell.is_empty()) else {
+            return self.default_shell();
+        };
+        let allowed = if requested.contains(['/', '\\']) {
+            self.allowed_shells.iter().find(|shell| shell.as_str() == requested)
+        } else {
+            self.allowed_shells.iter().find(|shell| {
+                let name = Path::new(shell.as_str()).file_name().and_then(|name| name.to_str());
+                name.is_some_and(|name| {
+                    name.eq_ignore_ascii_case(requested)
+                        || Path::new(name).file_stem().is_some_and(|stem| stem.eq_ignore_ascii_case(requested))
+                })
+            })
+        };
+        allowed
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Shell {} is not allowed by policy", requested))
+    }
+
+    fn default_shell(&self) -> Result<String> {
+        let from_env = std::env::var(if cfg!(windows) { "COMSPEC" } else { "SHELL" }).ok();
+        self.default_shell
+            .iter()
+            .chain(from_env.iter())
+            .find(|shell| self.allowed_shells.contains(shell))
+            .or_else(|| self.allowed_shells.iter().find(|shell| Path::new(shell.as_str()).exists()))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No shell is allowed by policy"))
+    }
+
+    /// Working directory for a request: the requested one if it exists,
+    /// otherwise the configured default or the home directory
+    pub fn resolve_cwd(&self, requested: Option<&str>) -> Result<PathBuf> {
+        if let Some(requested) = requested.filter(|cwd| !cwd.is_empty()) {
+            let path = PathBuf::from(requested);
+            if !path.is_dir() {
+                bail!("Working directory {} does not exist", requested);
+            }
+            return Ok(path);
+        }
+        Ok(self
+            .default_cwd
+            .clone()
+            .or_else(dirs::home_dir)
+            .filter(|path| path.is_dir())
+            .unwrap_or_else(std::env::temp_dir))
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+}
+
+/// Terminal size in character cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PtySize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for PtySize {
+    fn default() -> Self {
+        Self { cols: 80, rows: 24 }
+    }
+}
+
+impl PtySize {
+    /// A zero dimension, as sent by a viewer that isn't laid out yet, keeps the default
+    pub fn new(cols: u16, rows: u16) -> Self {
+        let default = Self::default();
+        Self {
+            cols: if cols == 0 { default.cols } else { cols },
+            rows: if rows == 0 { default.rows } else { rows },
+        }
+    }
+}
+
+/// What to run on a new terminal
+#[derive(Debug, Clone)]
+pub struct PtyCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: PathBuf,
+    /// Set on top of the agent's own environment
+    pub env: HashMap<String, String>,
+    pub size: PtySize,
+}
+
+/// The last `capacity` bytes a terminal printed
+#[derive(Debug)]
+pub struct Scrollback {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl Scrollback {
+    pub fn new(capacity: usize) -> Self {
+        Self { buffer: VecDeque::new(), capacity }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buffer.len() + data.len()).saturating_sub(self.capacity);
+        self.buffer.drain(..overflow);
+        self.buffer.extend(data);
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.iter().copied().collect()
+    }
+}
+
+/// One open terminal
+struct Terminal {
+    pty: Arc<Pty>,
+    shell: String,
+    /// Typed input, written in order by the terminal's writer thread
+    input: std::sync::mpsc::Sender<Vec<u8>>,
+    scrollback: Mutex<Scrollback>,
+    last_activity: Mutex<Instant>,
+    /// Why the agent closed the terminal; unset when the shell exited by itself
+    close_reason: Mutex<Option<String>>,
+}
+
+impl Terminal {
+    fn touch(&self) {
+        *self.last_activity.lock() = Instant::now();
+    }
+
+    fn close(&self, reason: &str) {
+        self.close_reason.lock().get_or_insert_with(|| reason.to_string());
+        self.pty.terminate();
+    }
+}
+
+/// Open terminals, keyed by session
+pub struct TerminalManager {
+    policy: TerminalPolicy,
+    outbox: mpsc::Sender<RelayMessage>,
+    terminals: Mutex<HashMap<String, Arc<Terminal>>>,
+}
+
+impl TerminalManager {
+    pub fn new(policy: TerminalPolicy, outbox: mpsc::Sender<RelayMessage>) -> Self {
+        Self {
+            policy,
+            outbox,
+            terminals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Handle a terminal message from the technician
+    pub async fn handle_message(self: &Arc<Self>, message: RelayMessage) -> Result<()> {
+        match message {
+            RelayMessage::TerminalOpen { session_id, shell, cols, rows, cwd, env } => {
+                let size = PtySize::new(cols, rows);
+                if let Err(e) = self.open(&session_id, shell.as_deref(), size, cwd.as_deref(), env).await {
+                    warn!("Failed to open terminal for session {}: {:#}", session_id, e);
+                    self.send(RelayMessage::TerminalClosed {
+                        session_id,
+                        exit_code: None,
+                        reason: Some(format!("{:#}", e)),
+                    })
+                    .await?;
+                }
+                Ok(())
+            }
+            RelayMessage::TerminalInput { session_id, data } => self.write_input(&session_id, data.into_bytes()),
+            RelayMessage::TerminalResize { session_id, cols, rows } => {
+                self.resize(&session_id, PtySize::new(cols, rows))
+            }
+            RelayMessage::TerminalClose { session_id } => {
+                self.close(&session_id, "closed by technician");
+                Ok(())
+            }
+            RelayMessage::TerminalScrollbackRequest { session_id } => {
+                let data = self
+                    .scrollback(&session_id)
+                    .with_context(|| format!("No terminal open for session {}", session_id))?;
+                self.send(RelayMessage::TerminalScrollback { session_id, data }).await
+            }
+            other => bail!("Not a terminal message: {:?}", other),
+        }
+    }
+
+    /// Start a shell on a new terminal for `session_id`
+    pub async fn open(
+        self: &Arc<Self>,
+        session_id: &str,
+        shell: Option<&str>,
+        size: PtySize,
+        cwd: Option<&str>,
+        env: HashMap<String, String>,
+    ) -> Result<()> {
+        let program = self.policy.resolve_shell(shell)?;
+        let cwd = self.policy.resolve_cwd(cwd)?;
+        let mut command = PtyCommand { program, args: Vec::new(), cwd, env, size };
+        if cfg!(unix) {
+            command.env.entry("TERM".to_string()).or_insert_with(|| "xterm-256color".to_string());
+        }
+
+        let (terminal, reader, writer, input_rx) = {
+            let mut terminals = self.terminals.lock();
+            if terminals.contains_key(session_id) {
+                bail!("Session {} already has a terminal open", session_id);
+            }
+            if terminals.len() >= self.policy.max_terminals {
+                bail!("Too many terminals open ({})", terminals.len());
+            }
+
+            let pty = Arc::new(Pty::spawn(&command)?);
+            let (reader, writer) = match pty.reader().and_then(|reader| Ok((reader, pty.writer()?))) {
+                Ok(handles) => handles,
+                Err(e) => {
+                    pty.terminate();
+                    return Err(e);
+                }
+            };
+            let (input, input_rx) = std::sync::mpsc::channel();
+            let terminal = Arc::new(Terminal {
+                pty,
+                shell: command.program.clone(),
+                input,
+                scrollback: Mutex::new(Scrollback::new(self.policy.scrollback_bytes)),
+                last_activity: Mutex::new(Instant::now()),
+                close_reason: Mutex::new(None),
+            });
+            terminals.insert(session_id.to_string(), Arc::clone(&terminal));
+            (terminal, reader, writer, input_rx)
+        };
+
+        let pid = terminal.pty.pid();
+        info!("Opened terminal for session {}: {} (pid {})", session_id, command.program, pid);
+        // Sent before the reader starts, so it always precedes the output
+        let opened = RelayMessage::TerminalOpened {
+            session_id: session_id.to_string(),
+            shell: command.program,
+            pid,
+        };
+        if let Err(e) = self.send(opened).await {
+            debug!("Failed to announce terminal for session {}: {}", session_id, e);
+        }
+
+        std::thread::spawn(move || write_input(writer, input_rx));
+        let manager = Arc::clone(self);
+        let session_id = session_id.to_string();
+        std::thread::spawn(move || manager.pump_output(session_id, terminal, reader));
+        Ok(())
+    }
+
+    /// Type `data` into a session's terminal
+    pub fn write_input(&self, session_id: &str, data: Vec<u8>) -> Result<()> {
+        let terminal = self.terminal(session_id)?;
+        terminal.touch();
+        terminal
+            .input
+            .send(data)
+            .map_err(|_| anyhow::anyhow!("Terminal for session {} is closing", session_id))
+    }
+
+    pub fn resize(&self, session_id: &str, size: PtySize) -> Result<()> {
+        let terminal = self.terminal(session_id)?;
+        terminal.touch();
+        terminal.pty.resize(size)
+    }
+
+    /// Recent output of a session's terminal
+    pub fn scrollback(&self, session_id: &str) -> Option<Vec<u8>> {
+        let terminals = self.terminals.lock();
+        terminals.get(session_id).map(|terminal| terminal.scrollback.lock().contents())
+    }
+
+    /// Hang up a session's terminal; `TerminalClosed` follows once the shell is gone
+    pub fn close(&self, session_id: &str, reason: &str) -> bool {
+        let Some(terminal) = self.terminals.lock().remove(session_id) else {
+            return false;
+        };
+        info!("Closing terminal for session {}: {}", session_id, reason);
+        terminal.close(reason);
+        true
+    }
+
+    pub fn close_all(&self, reason: &str) {
+        let terminals: Vec<_> = self.terminals.lock().drain().collect();
+        for (session_id, terminal) in terminals {
+            info!("Closing terminal for session {}: {}", session_id, reason);
+            terminal.close(reason);
+        }
+    }
+
+    /// Close terminals idle for longer than the policy allows
+    pub fn close_idle(&self) -> Vec<String> {
+        let Some(timeout) = self.policy.idle_timeout() else {
+            return Vec::new();
+        };
+        let idle: Vec<String> = self
+            .terminals
+            .lock()
+            .iter()
+            .filter(|(_, terminal)| terminal.last_activity.lock().elapsed() >= timeout)
+            .map(|(session_id, _)| session_id.clone())
+            .collect();
+        for session_id in &idle {
+            self.close(session_id, "idle timeout");
+        }
+        idle
+    }
+
+    pub fn count(&self) -> usize {
+        self.terminals.lock().len()
+    }
+
+    fn terminal(&self, session_id: &str) -> Result<Arc<Terminal>> {
+        self.terminals
+            .lock()
+            .get(session_id)
+            .cloned()
+            .with_context(|| format!("No terminal open for session {}", session_id))
+    }
+
+    async fn send(&self, message: RelayMessage) -> Result<()> {
+        self.outbox.send(message).await.context("Agent message channel closed")
+    }
+
+    /// Stream a terminal's output until the shell is gone, then report it closed.
+    /// Runs on its own thread; PTY reads block.
+    fn pump_output(&self, session_id: String, terminal: Arc<Terminal>, mut reader: Box<dyn Read + Send>) {
+        let mut buffer = vec![0u8; READ_CHUNK];
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("Terminal for session {} stopped reading: {}", session_id, e);
+                    break;
+                }
+            };
+            let data = buffer[..read].to_vec();
+            terminal.scrollback.lock().push(&data);
+            terminal.touch();
+            let output = RelayMessage::TerminalOutput { session_id: session_id.clone(), data };
+            if self.outbox.blocking_send(output).is_err() {
+                terminal.close("agent shutting down");
+                break;
+            }
+        }
+
+        let exit_code = terminal.pty.wait();
+        {
+            // A new terminal may already have replaced this one
+            let mut terminals = self.terminals.lock();
+            if terminals.get(&session_id).is_some_and(|current| Arc::ptr_eq(current, &terminal)) {
+                terminals.remove(&session_id);
+            }
+        }
+        let reason = terminal.close_reason.lock().take();
+        info!(
+            "Terminal for session {} closed ({}), exit code {:?}",
+            session_id,
+            reason.as_deref().unwrap_or("shell exited"),
+            exit_code
+        );
+        let _ = self.outbox.blocking_send(RelayMessage::TerminalClosed { session_id, exit_code, reason });
+    }
+}
+
+/// Write typed input to the terminal until it closes
+fn write_input(mut writer: Box<dyn Write + Send>, input: std::sync::mpsc::Receiver<Vec<u8>>) {
+    for data in input {
+        if let Err(e) = writer.write_all(&data).and_then(|_| writer.flush()) {
+            debug!("Terminal stopped accepting input: {}", e);
+            break;
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn manager(policy: TerminalPolicy) -> (Arc<TerminalManager>, mpsc::Receiver<RelayMessage>) {
+        let (tx, rx) = mpsc::channel(256);
+        (Arc::new(TerminalManager::new(policy, tx)), rx)
+    }
+
+    fn sh_policy() -> TerminalPolicy {
+        TerminalPolicy {
+            allowed_shells: vec!["/bin/sh".to_string()],
+            ..TerminalPolicy::default()
+        }
+    }
+
+    /// Collect output until it contains `needle`
+    async fn output_until(rx: &mut mpsc::Receiver<RelayMessage>, needle: &str) -> String {
+        let mut output = String::new();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while !output.contains(needle) {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(RelayMessage::TerminalOutput { data, .. })) => output.push_str(&String::from_utf8_lossy(&data)),
+                Ok(Some(_)) => {}
+                _ => panic!("{:?} never appeared in {:?}", needle, output),
+            }
+        }
+        output
+    }
+
+    async fn closed(rx: &mut mpsc::Receiver<RelayMessage>) -> (String, Option<i32>, Option<String>) {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(RelayMessage::TerminalClosed { session_id, exit_code, reason })) => {
+                    return (session_id, exit_code, reason)
+                }
+                Ok(Some(_)) => {}
+                _ => panic!("terminal never closed"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_shell_allowlist() {
+        let policy = TerminalPolicy {
+            allowed_shells: vec!["/bin/sh".to_string(), "/usr/bin/pwsh.exe".to_string()],
+            default_shell: Some("/bin/zsh".to_string()),
+            ..TerminalPolicy::default()
+        };
+        assert_eq!(policy.resolve_shell(Some("/bin/sh")).unwrap(), "/bin/sh");
+        assert_eq!(policy.resolve_shell(Some("sh")).unwrap(), "/bin/sh");
+        assert_eq!(policy.resolve_shell(Some("pwsh")).unwrap(), "/usr/bin/pwsh.exe");
+        assert!(policy.resolve_shell(Some("/tmp/sh")).is_err());
+        assert!(policy.resolve_shell(Some("bash")).is_err());
+        // A default that isn't allowed is skipped
+        assert_eq!(policy.resolve_shell(None).unwrap(), "/bin/sh");
+
+        let disabled = TerminalPolicy { enabled: false, ..policy };
+        assert!(disabled.resolve_shell(Some("sh")).is_err());
+    }
+
+    #[test]
+    fn test_scrollback_keeps_latest_bytes() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"hello ");
+        scrollback.push(b"world");
+        assert_eq!(scrollback.contents(), b"lo world");
+        scrollback.push(b"0123456789");
+        assert_eq!(scrollback.contents(), b"23456789");
+    }
+
+    #[tokio::test]
+    async fn test_terminal_session() {
+        let (terminals, mut rx) = manager(sh_policy());
+        let env = HashMap::from([("GHOSTLINK_GREETING".to_string(), "hi-there".to_string())]);
+        let cwd = std::env::temp_dir();
+        terminals.open("s1", Some("sh"), PtySize::new(80, 24), cwd.to_str(), env).await.unwrap();
+        match rx.recv().await {
+            Some(RelayMessage::TerminalOpened { session_id, shell, .. }) => {
+                assert_eq!(session_id, "s1");
+                assert_eq!(shell, "/bin/sh");
+            }
+            other => panic!("expected TerminalOpened, got {:?}", other),
+        }
+
+        terminals.write_input("s1", b"echo $GHOSTLINK_GREETING; stty size\n".to_vec()).unwrap();
+        output_until(&mut rx, "24 80").await;
+        assert!(String::from_utf8_lossy(&terminals.scrollback("s1").unwrap()).contains("hi-there"));
+
+        terminals.resize("s1", PtySize::new(100, 40)).unwrap();
+        terminals.write_input("s1", b"stty size; pwd\n".to_vec()).unwrap();
+        output_until(&mut rx, "40 100").await;
+
+        terminals.write_input("s1", b"exit 7\n".to_vec()).unwrap();
+        assert_eq!(closed(&mut rx).await, ("s1".to_string(), Some(7), None));
+        assert_eq!(terminals.count(), 0);
+        assert!(terminals.write_input("s1", b"echo gone\n".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_terminals_and_close() {
+        let policy = TerminalPolicy { max_terminals: 2, ..sh_policy() };
+        let (terminals, mut rx) = manager(policy);
+        terminals.open("a", None, PtySize::default(), None, HashMap::new()).await.unwrap();
+        terminals.open("b", None, PtySize::default(), None, HashMap::new()).await.unwrap();
+        assert!(terminals.open("a", None, PtySize::default(), None, HashMap::new()).await.is_err());
+        assert!(terminals.open("c", None, PtySize::default(), None, HashMap::new()).await.is_err());
+        assert!(terminals.open("d", Some("bash"), PtySize::default(), None, HashMap::new()).await.is_err());
+
+        assert!(terminals.close("a", "closed by technician"));
+        let (session_id, _, reason) = closed(&mut rx).await;
+        assert_eq!(session_id, "a");
+        assert_eq!(reason.as_deref(), Some("closed by technician"));
+
+        terminals.write_input("b", b"echo still-here\n".to_vec()).unwrap();
+        output_until(&mut rx, "still-here").await;
+        terminals.close_all("session ended");
+        assert_eq!(closed(&mut rx).await.0, "b");
+    }
+
+    #[tokio::test]
+    async fn test_idle_terminal_is_closed() {
+        let policy = TerminalPolicy { idle_timeout_secs: 1, ..sh_policy() };
+        let (terminals, mut rx) = manager(policy);
+        terminals.open("idle", None, PtySize::default(), None, HashMap::new()).await.unwrap();
+        assert!(terminals.close_idle().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(terminals.close_idle(), vec!["idle".to_string()]);
+        let (_, _, reason) = closed(&mut rx).await;
+        assert_eq!(reason.as_deref(), Some("idle timeout"));
+    }
+}
@@ -0,0 +1,210 @@
+//! Pseudo-terminals on Unix
+//!
+//! The shell gets the slave side as its controlling terminal in a session of
+//! its own, so job control and `SIGWINCH` work as they would in a local
+//! terminal emulator. The agent keeps the master side.
+
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+
+use super::{PtyCommand, PtySize};
+
+pub struct Pty {
+    master: File,
+    pid: u32,
+    child: Mutex<Child>,
+}
+
+impl Pty {
+    /// Start `command` on a new pseudo-terminal
+    pub fn spawn(command: &PtyCommand) -> Result<Self> {
+        let (master, slave) = open_pair(command.size)?;
+
+        let mut process = Command::new(&command.program);
+        process
+            .args(&command.args)
+            .current_dir(&command.cwd)
+            .envs(&command.env)
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            process.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = process
+            .spawn()
+            .with_context(|| format!("Failed to start {}", command.program))?;
+        // `process` still holds the slave; the master only sees EOF once every copy is closed
+        drop(process);
+
+        Ok(Self { master, pid: child.id(), child: Mutex::new(child) })
+    }
+
+    /// Whether this machine hands out pseudo-terminals, e.g. not in a
+    /// container without /dev/pts
+    pub fn available() -> bool {
+        open_pair(PtySize::default()).is_ok()
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Output of the terminal; reads end once the shell and everything it
+    /// started have closed the terminal
+    pub fn reader(&self) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(MasterReader(self.master.try_clone()?)))
+    }
+
+    /// Input to the terminal, as if typed
+    pub fn writer(&self) -> Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.master.try_clone()?))
+    }
+
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        set_size(self.master.as_raw_fd(), size).context("Failed to resize terminal")
+    }
+
+    /// Hang up on the shell's session, as closing a terminal window does
+    pub fn terminate(&self) {
+        // The shell leads its own process group, see `spawn`
+        unsafe { libc::kill(-(self.pid as libc::pid_t), libc::SIGHUP) };
+    }
+
+    /// Wait for the shell to exit; `None` when a signal ended it
+    pub fn wait(&self) -> Option<i32> {
+        self.child.lock().wait().ok().and_then(|status| status.code())
+    }
+}
+
+/// Linux reports a hung-up master as `EIO` rather than end of file
+struct MasterReader(File);
+
+impl Read for MasterReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+fn open_pair(size: PtySize) -> Result<(File, File)> {
+    let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to open a pseudo-terminal");
+    }
+    // SAFETY: posix_openpt returned a new descriptor that nothing else owns
+    let master = unsafe { File::from_raw_fd(fd) };
+
+    // The shell must not inherit the master
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1
+        || unsafe { libc::grantpt(fd) } != 0
+        || unsafe { libc::unlockpt(fd) } != 0
+    {
+        return Err(io::Error::last_os_error()).context("Failed to prepare the pseudo-terminal");
+    }
+
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(slave_name(fd)?)
+        .context("Failed to open the terminal's slave side")?;
+    set_size(fd, size)?;
+    Ok((master, slave))
+}
+
+#[cfg(target_os = "linux")]
+fn slave_name(fd: RawFd) -> Result<String> {
+    let mut buffer = [0 as libc::c_char; 128];
+    let result = unsafe { libc::ptsname_r(fd, buffer.as_mut_ptr(), buffer.len()) };
+    if result != 0 {
+        bail!("Failed to name the terminal's slave side: {}", io::Error::from_raw_os_error(result));
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn slave_name(fd: RawFd) -> Result<String> {
+    // ptsname returns a static buffer
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _guard = LOCK.lock();
+    let name = unsafe { libc::ptsname(fd) };
+    if name.is_null() {
+        bail!("Failed to name the terminal's slave side: {}", io::Error::last_os_error());
+    }
+    Ok(unsafe { std::ffi::CStr::from_ptr(name) }.to_string_lossy().into_owned())
+}
+
+fn set_size(fd: RawFd, size: PtySize) -> io::Result<()> {
+    let winsize = libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsize) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sh(script: &str, size: PtySize) -> PtyCommand {
+        PtyCommand {
+            program: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            cwd: std::env::temp_dir(),
+            env: HashMap::from([("GHOSTLINK_TEST".to_string(), "from-env".to_string())]),
+            size,
+        }
+    }
+
+    fn read_all(pty: &Pty) -> String {
+        let mut output = Vec::new();
+        pty.reader().unwrap().read_to_end(&mut output).unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[test]
+    fn test_runs_command_on_terminal() {
+        let pty = Pty::spawn(&sh("echo hello $GHOSTLINK_TEST; stty size; test -t 0", PtySize { cols: 120, rows: 30 })).unwrap();
+        let output = read_all(&pty);
+        assert!(output.contains("hello from-env"), "{:?}", output);
+        assert!(output.contains("30 120"), "{:?}", output);
+        assert_eq!(pty.wait(), Some(0));
+    }
+
+    #[test]
+    fn test_exit_code_and_hangup() {
+        let pty = Pty::spawn(&sh("exit 3", PtySize::default())).unwrap();
+        read_all(&pty);
+        assert_eq!(pty.wait(), Some(3));
+
+        let pty = Pty::spawn(&sh("sleep 30", PtySize::default())).unwrap();
+        pty.terminate();
+        read_all(&pty);
+        assert_eq!(pty.wait(), None);
+    }
+}
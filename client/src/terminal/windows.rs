@@ -0,0 +1,273 @@
+//! Pseudo consoles on Windows (ConPTY)
+//!
+//! The shell runs attached to a pseudo console that translates its console
+//! API calls into VT sequences on a pipe. The console keeps that pipe open
+//! after the shell exits, so a watcher closes it when the process ends,
+//! which is what ends the output stream.
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use std::ffi::{c_void, OsString};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::FromRawHandle;
+use std::sync::Arc;
+use windows::core::{HSTRING, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE};
+use windows::Win32::System::Console::{ClosePseudoConsole, CreatePseudoConsole, ResizePseudoConsole, COORD, HPCON};
+use windows::Win32::System::Pipes::CreatePipe;
+use windows::Win32::System::Threading::{
+    CreateProcessW, DeleteProcThreadAttributeList, GetExitCodeProcess, InitializeProcThreadAttributeList,
+    TerminateProcess, UpdateProcThreadAttribute, WaitForSingleObject, CREATE_UNICODE_ENVIRONMENT,
+    EXTENDED_STARTUPINFO_PRESENT, INFINITE, LPPROC_THREAD_ATTRIBUTE_LIST, PROCESS_INFORMATION,
+    PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE, STARTUPINFOEXW,
+};
+
+use super::{PtyCommand, PtySize};
+
+/// Process handle, closed once neither the terminal nor its watcher needs it
+struct Process(HANDLE);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.0);
+        }
+    }
+}
+
+/// Pseudo console handle; closing it twice is undefined
+struct Console(Mutex<Option<HPCON>>);
+
+impl Console {
+    fn close(&self) {
+        if let Some(console) = self.0.lock().take() {
+            unsafe { ClosePseudoConsole(console) };
+        }
+    }
+}
+
+pub struct Pty {
+    console: Arc<Console>,
+    input: File,
+    output: File,
+    process: Arc<Process>,
+    pid: u32,
+}
+
+impl Pty {
+    /// Start `command` on a new pseudo console
+    pub fn spawn(command: &PtyCommand) -> Result<Self> {
+        let (mut input_read, mut input_write) = (HANDLE::default(), HANDLE::default());
+        let (mut output_read, mut output_write) = (HANDLE::default(), HANDLE::default());
+        unsafe {
+            CreatePipe(&mut input_read, &mut input_write, None, 0).context("Failed to create terminal input pipe")?;
+            CreatePipe(&mut output_read, &mut output_write, None, 0).context("Failed to create terminal output pipe")?;
+        }
+        // SAFETY: CreatePipe returned new handles that nothing else owns
+        let input = unsafe { File::from_raw_handle(input_write.0 as *mut c_void) };
+        let output = unsafe { File::from_raw_handle(output_read.0 as *mut c_void) };
+
+        // The console duplicates the ends it is given
+        let console = unsafe { CreatePseudoConsole(coord(command.size), input_read, output_write, 0) };
+        unsafe {
+            let _ = CloseHandle(input_read);
+            let _ = CloseHandle(output_write);
+        }
+        let console = Arc::new(Console(Mutex::new(Some(console.context("Failed to create pseudo console")?))));
+
+        let process = match start_process(command, &console) {
+            Ok(process) => process,
+            Err(e) => {
+                console.close();
+                return Err(e);
+            }
+        };
+        let pid = process.1;
+        let process = Arc::new(process.0);
+
+        let watched = (Arc::clone(&process), Arc::clone(&console));
+        std::thread::spawn(move || {
+            let (process, console) = watched;
+            unsafe { WaitForSingleObject(process.0, INFINITE) };
+            console.close();
+        });
+
+        Ok(Self { console, input, output, process, pid })
+    }
+
+    /// ConPTY ships with every Windows the agent supports
+    pub fn available() -> bool {
+        true
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Output of the console; reads end once the shell has exited
+    pub fn reader(&self) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.output.try_clone()?))
+    }
+
+    /// Input to the console, as if typed
+    pub fn writer(&self) -> Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.input.try_clone()?))
+    }
+
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        match *self.console.0.lock() {
+            Some(console) => unsafe { ResizePseudoConsole(console, coord(size)) }.context("Failed to resize terminal"),
+            None => Ok(()),
+        }
+    }
+
+    pub fn terminate(&self) {
+        unsafe {
+            let _ = TerminateProcess(self.process.0, 1);
+        }
+    }
+
+    /// Wait for the shell to exit
+    pub fn wait(&self) -> Option<i32> {
+        let mut code = 0u32;
+        unsafe {
+            WaitForSingleObject(self.process.0, INFINITE);
+            GetExitCodeProcess(self.process.0, &mut code).ok()?;
+        }
+        Some(code as i32)
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        self.console.close();
+    }
+}
+
+fn coord(size: PtySize) -> COORD {
+    COORD {
+        X: size.cols.min(i16::MAX as u16) as i16,
+        Y: size.rows.min(i16::MAX as u16) as i16,
+    }
+}
+
+/// Create the shell's process attached to `console`
+fn start_process(command: &PtyCommand, console: &Console) -> Result<(Process, u32)> {
+    let Some(console) = *console.0.lock() else {
+        anyhow::bail!("Pseudo console is closed");
+    };
+
+    let mut size = 0usize;
+    // The first call only reports the size the list needs
+    let _ = unsafe { InitializeProcThreadAttributeList(LPPROC_THREAD_ATTRIBUTE_LIST::default(), 1, 0, &mut size) };
+    let mut attributes = vec![0u8; size];
+    let list = LPPROC_THREAD_ATTRIBUTE_LIST(attributes.as_mut_ptr().cast());
+    unsafe { InitializeProcThreadAttributeList(list, 1, 0, &mut size) }.context("Failed to set up process attributes")?;
+
+    let result = (|| {
+        unsafe {
+            UpdateProcThreadAttribute(
+                list,
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE as usize,
+                Some(console.0 as *const c_void),
+                std::mem::size_of::<HPCON>(),
+                None,
+                None,
+            )
+        }
+        .context("Failed to attach pseudo console")?;
+
+        let mut startup = STARTUPINFOEXW::default();
+        startup.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
+        startup.lpAttributeList = list;
+        let mut command_line = command_line(command);
+        let environment = environment_block(command);
+        let cwd = HSTRING::from(command.cwd.as_os_str());
+        let mut info = PROCESS_INFORMATION::default();
+        unsafe {
+            CreateProcessW(
+                PCWSTR::null(),
+                PWSTR(command_line.as_mut_ptr()),
+                None,
+                None,
+                BOOL::from(false),
+                EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
+                Some(environment.as_ptr().cast()),
+                &cwd,
+                &startup.StartupInfo,
+                &mut info,
+            )
+        }
+        .with_context(|| format!("Failed to start {}", command.program))?;
+        unsafe {
+            let _ = CloseHandle(info.hThread);
+        }
+        Ok((Process(info.hProcess), info.dwProcessId))
+    })();
+
+    unsafe { DeleteProcThreadAttributeList(list) };
+    result
+}
+
+/// Program and arguments quoted the way the C runtime splits them
+fn command_line(command: &PtyCommand) -> Vec<u16> {
+    let mut line = quote(&command.program);
+    for arg in &command.args {
+        line.push(' ');
+        line.push_str(&quote(arg));
+    }
+    line.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.extend(std::iter::repeat('\\').take(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+                continue;
+            }
+            _ => {}
+        }
+        if c != '\\' {
+            quoted.extend(std::iter::repeat('\\').take(backslashes));
+            backslashes = 0;
+            quoted.push(c);
+        }
+    }
+    quoted.extend(std::iter::repeat('\\').take(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// The agent's environment with the request's overrides, sorted
+/// case-insensitively as CreateProcess expects
+fn environment_block(command: &PtyCommand) -> Vec<u16> {
+    let mut variables: Vec<(OsString, OsString)> = std::env::vars_os()
+        .filter(|(key, _)| {
+            !command.env.keys().any(|name| name.eq_ignore_ascii_case(&key.to_string_lossy()))
+        })
+        .collect();
+    variables.extend(command.env.iter().map(|(key, value)| (key.into(), value.into())));
+    variables.sort_by_key(|(key, _)| key.to_string_lossy().to_uppercase());
+
+    let mut block = Vec::new();
+    for (key, value) in variables {
+        block.extend(key.encode_wide());
+        block.push('=' as u16);
+        block.extend(value.encode_wide());
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
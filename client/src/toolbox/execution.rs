@@ -0,0 +1,319 @@
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::sandbox::ToolSandbox;
+use crate::error::{Context, Result};
+
+/// Events buffered between the child's pipes and the consumer
+const EVENT_BUFFER: usize = 256;
+
+/// Limits applied to a running tool
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutionLimits {
+    /// Kill the tool after this long; `None` lets it run until it exits
+    pub timeout: Option<Duration>,
+    /// Stop forwarding output after this many bytes of stdout and stderr combined
+    pub max_output_bytes: usize,
+}
+
+/// How a tool execution ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolExitStatus {
+    /// The process exited on its own; `None` if it was killed by a signal
+    Exited(Option<i32>),
+    /// Killed after exceeding the timeout
+    TimedOut,
+    /// Killed on the caller's request
+    Cancelled,
+}
+
+/// Output of a running tool, in the order it was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolOutputEvent {
+    Stdout(String),
+    Stderr(String),
+    /// The output cap was reached; later lines are discarded
+    Truncated,
+    /// Always the last event
+    Finished(ToolExitStatus),
+}
+
+/// Collected output of a finished tool
+#[derive(Debug, Clone)]
+pub struct ToolOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: ToolExitStatus,
+    pub truncated: bool,
+}
+
+/// A running tool. Dropping the handle cancels the tool.
+pub struct ToolExecutionHandle {
+    pub execution_id: Uuid,
+    events: mpsc::Receiver<ToolOutputEvent>,
+    cancel_tx: watch::Sender<bool>,
+}
+
+impl ToolExecutionHandle {
+    /// Next output event; `None` after `Finished` has been returned
+    pub async fn next_event(&mut self) -> Option<ToolOutputEvent> {
+        self.events.recv().await
+    }
+
+    /// Kill the tool; a `Finished(Cancelled)` event follows
+    pub fn cancel(&self) {
+        let _ = self.cancel_tx.send(true);
+    }
+
+    /// Wait for the tool to finish and collect its output
+    pub async fn wait(mut self) -> ToolOutput {
+        let mut output = ToolOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+            status: ToolExitStatus::Exited(None),
+            truncated: false,
+        };
+
+        while let Some(event) = self.next_event().await {
+            match event {
+                ToolOutputEvent::Stdout(line) => {
+                    output.stdout.push_str(&line);
+                    output.stdout.push('\n');
+                }
+                ToolOutputEvent::Stderr(line) => {
+                    output.stderr.push_str(&line);
+                    output.stderr.push('\n');
+                }
+                ToolOutputEvent::Truncated => output.truncated = true,
+                ToolOutputEvent::Finished(status) => output.status = status,
+            }
+        }
+
+        output
+    }
+}
+
+/// Spawn `program` with piped output and stream it through the returned handle
+pub fn spawn_tool(
+    program: &Path,
+    args: &[String],
+    working_dir: Option<&Path>,
+    limits: ExecutionLimits,
+) -> Result<ToolExecutionHandle> {
+    spawn_tool_in(program, args, working_dir, limits, &ToolSandbox::None)
+}
+
+/// [`spawn_tool`], confined by `sandbox` to `working_dir`
+pub fn spawn_tool_in(
+    program: &Path,
+    args: &[String],
+    working_dir: Option<&Path>,
+    limits: ExecutionLimits,
+    sandbox: &ToolSandbox,
+) -> Result<ToolExecutionHandle> {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(dir) = working_dir {
+        command.current_dir(dir);
+    }
+    sandbox.apply(&mut command, working_dir)?;
+
+    let child = command.spawn()
+        .with_context(|| format!("Failed to start {}", program.display()))?;
+
+    let execution_id = Uuid::new_v4();
+    let (events_tx, events) = mpsc::channel(EVENT_BUFFER);
+    let (cancel_tx, cancel_rx) = watch::channel(false);
+
+    debug!("Started tool execution {} ({})", execution_id, program.display());
+    tokio::spawn(run_child(child, events_tx, cancel_rx, limits, execution_id));
+
+    Ok(ToolExecutionHandle {
+        execution_id,
+        events,
+        cancel_tx,
+    })
+}
+
+/// Forwards output until the output cap is reached
+struct OutputBudget {
+    remaining: usize,
+    truncated: bool,
+}
+
+impl OutputBudget {
+    async fn forward(&mut self, events_tx: &mpsc::Sender<ToolOutputEvent>, event: ToolOutputEvent) {
+        if self.truncated {
+            return;
+        }
+
+        let len = match &event {
+            ToolOutputEvent::Stdout(line) | ToolOutputEvent::Stderr(line) => line.len() + 1,
+            _ => 0,
+        };
+        if len > self.remaining {
+            self.truncated = true;
+            let _ = events_tx.send(ToolOutputEvent::Truncated).await;
+            return;
+        }
+
+        self.remaining -= len;
+        let _ = events_tx.send(event).await;
+    }
+}
+
+async fn run_child(
+    mut child: Child,
+    events_tx: mpsc::Sender<ToolOutputEvent>,
+    mut cancel_rx: watch::Receiver<bool>,
+    limits: ExecutionLimits,
+    execution_id: Uuid,
+) {
+    let mut stdout = child.stdout.take().map(|pipe| BufReader::new(pipe).lines());
+    let mut stderr = child.stderr.take().map(|pipe| BufReader::new(pipe).lines());
+    let mut budget = OutputBudget {
+        remaining: limits.max_output_bytes,
+        truncated: false,
+    };
+
+    let deadline = async {
+        match limits.timeout {
+            Some(timeout) => sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+
+    // Cancellation and the timeout win over pending output
+    let status = loop {
+        tokio::select! {
+            biased;
+            _ = cancelled(&mut cancel_rx) => break ToolExitStatus::Cancelled,
+            _ = &mut deadline => break ToolExitStatus::TimedOut,
+            line = next_line(&mut stdout), if stdout.is_some() => match line {
+                Some(line) => budget.forward(&events_tx, ToolOutputEvent::Stdout(line)).await,
+                None => stdout = None,
+            },
+            line = next_line(&mut stderr), if stderr.is_some() => match line {
+                Some(line) => budget.forward(&events_tx, ToolOutputEvent::Stderr(line)).await,
+                None => stderr = None,
+            },
+            exit = child.wait(), if stdout.is_none() && stderr.is_none() => {
+                break ToolExitStatus::Exited(exit.ok().and_then(|status| status.code()));
+            }
+        }
+    };
+
+    if matches!(status, ToolExitStatus::TimedOut | ToolExitStatus::Cancelled) {
+        if let Err(e) = child.kill().await {
+            warn!("Failed to kill tool execution {}: {}", execution_id, e);
+        }
+    }
+
+    debug!("Tool execution {} finished: {:?}", execution_id, status);
+    let _ = events_tx.send(ToolOutputEvent::Finished(status)).await;
+}
+
+async fn next_line<R>(lines: &mut Option<tokio::io::Lines<BufReader<R>>>) -> Option<String>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    match lines {
+        Some(lines) => lines.next_line().await.ok().flatten(),
+        None => None,
+    }
+}
+
+/// Resolves once cancellation is requested or the handle is dropped
+async fn cancelled(cancel_rx: &mut watch::Receiver<bool>) {
+    while !*cancel_rx.borrow() {
+        if cancel_rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn sh(script: &str, limits: ExecutionLimits) -> ToolExecutionHandle {
+        spawn_tool(Path::new("sh"), &["-c".to_string(), script.to_string()], None, limits).unwrap()
+    }
+
+    fn limits(timeout: Option<Duration>) -> ExecutionLimits {
+        ExecutionLimits { timeout, max_output_bytes: 1024 * 1024 }
+    }
+
+    async fn collect(handle: &mut ToolExecutionHandle) -> Vec<ToolOutputEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = handle.next_event().await {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_tool() {
+        let started = Instant::now();
+        let mut handle = sh("echo started; sleep 30", limits(Some(Duration::from_millis(300))));
+
+        let events = collect(&mut handle).await;
+        assert_eq!(events, vec![
+            ToolOutputEvent::Stdout("started".to_string()),
+            ToolOutputEvent::Finished(ToolExitStatus::TimedOut),
+        ]);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_running_tool() {
+        let mut handle = sh("while true; do echo tick; sleep 0.05; done", limits(None));
+
+        assert_eq!(handle.next_event().await, Some(ToolOutputEvent::Stdout("tick".to_string())));
+        handle.cancel();
+
+        let events = collect(&mut handle).await;
+        assert_eq!(events.last(), Some(&ToolOutputEvent::Finished(ToolExitStatus::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_output_order() {
+        let script = "echo out1; sleep 0.1; echo err1 >&2; sleep 0.1; echo out2; sleep 0.1; echo err2 >&2; exit 3";
+        let mut handle = sh(script, limits(None));
+
+        let events = collect(&mut handle).await;
+        assert_eq!(events, vec![
+            ToolOutputEvent::Stdout("out1".to_string()),
+            ToolOutputEvent::Stderr("err1".to_string()),
+            ToolOutputEvent::Stdout("out2".to_string()),
+            ToolOutputEvent::Stderr("err2".to_string()),
+            ToolOutputEvent::Finished(ToolExitStatus::Exited(Some(3))),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_output_cap_truncates() {
+        let handle = sh("for i in 1 2 3 4 5; do echo 123456789; done", ExecutionLimits {
+            timeout: None,
+            max_output_bytes: 25,
+        });
+
+        let output = handle.wait().await;
+        assert_eq!(output.stdout, "123456789\n123456789\n");
+        assert!(output.truncated);
+        assert_eq!(output.status, ToolExitStatus::Exited(Some(0)));
+    }
+}
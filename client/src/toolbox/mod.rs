@@ -1,15 +1,45 @@
 #![allow(dead_code)]
 
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use parking_lot::RwLock;
 use tokio::fs;
-use tracing::{debug, info};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 pub mod storage;
 pub mod server_sync;
+pub mod execution;
+pub mod policy;
+pub mod sandbox;
+pub mod template;
+pub mod queue;
+
+use template::ToolArg;
+use execution::{ExecutionLimits, ToolExecutionHandle, ToolExitStatus, ToolOutput};
+use policy::{ToolAuditEntry, ToolDecision, ToolPolicy};
+use sandbox::ToolSandbox;
+use server_sync::ServerSync;
+
+use crate::elevation::ElevatedContext;
+use crate::error::{GhostLinkError, Result, ToolError};
+
+/// Number of tool audit entries kept in memory
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// How often a running session re-syncs server-managed tools
+pub const SERVER_SYNC_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Serializes syncs and index writes between toolbox managers in this process;
+/// [`IndexLock`] does the same between processes. Writes also go through a
+/// rename, so a reader never sees a torn file.
+static INDEX_LOCK: Mutex<()> = Mutex::const_new(());
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tool {
@@ -25,6 +55,41 @@ pub struct Tool {
     pub requires_admin: bool,
     pub auto_update: bool,
     pub server_managed: bool,
+    /// Variables for `{{name}}` placeholders in `command`; without any,
+    /// `command` is the program path as-is
+    #[serde(default)]
+    pub args_template: Vec<ToolArg>,
+}
+
+impl Tool {
+    /// Program and arguments after substituting `vars` into the command
+    pub fn command_line(&self, vars: &HashMap<String, String>) -> Result<Vec<String>> {
+        if self.args_template.is_empty() {
+            if let Some(name) = vars.keys().next() {
+                return Err(GhostLinkError::Other(format!("Unknown variable: {}", name)));
+            }
+            return Ok(vec![self.command.clone()]);
+        }
+
+        let values = template::resolve_vars(&self.args_template, vars)?;
+        let rendered = template::render_command(&self.command, &values, &self.args_template)?;
+        let argv = template::split_command_line(&rendered)?;
+        if argv.is_empty() {
+            return Err(GhostLinkError::Other(format!("Command for tool '{}' is empty", self.name)));
+        }
+        Ok(argv)
+    }
+
+    /// Executable named by the command, for locating the installed copy
+    pub fn program(&self) -> String {
+        if self.args_template.is_empty() {
+            return self.command.clone();
+        }
+        template::split_command_line(&self.command)
+            .ok()
+            .and_then(|argv| argv.into_iter().next())
+            .unwrap_or_else(|| self.command.clone())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +102,32 @@ pub enum ToolCategory {
     Custom,
 }
 
+impl ToolCategory {
+    /// Name policies refer to the category by
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToolCategory::System => "system",
+            ToolCategory::Network => "network",
+            ToolCategory::Security => "security",
+            ToolCategory::Monitoring => "monitoring",
+            ToolCategory::Development => "development",
+            ToolCategory::Custom => "custom",
+        }
+    }
+
+    /// Map one of the server's toolbox categories
+    pub fn from_server_category(category: &str) -> Self {
+        match category {
+            "Network Tools" => ToolCategory::Network,
+            "Security Tools" => ToolCategory::Security,
+            "Performance Monitoring" => ToolCategory::Monitoring,
+            "System Information" | "Disk & File Tools" | "Registry Tools" | "Process Management"
+            | "Troubleshooting" | "NirSoft Tools" | "Sysinternals" => ToolCategory::System,
+            _ => ToolCategory::Custom,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolboxConfig {
     pub local_tools_path: PathBuf,
@@ -44,115 +135,332 @@ pub struct ToolboxConfig {
     pub auto_update_enabled: bool,
     pub organization_id: Option<Uuid>,
     pub user_id: Option<Uuid>,
+    /// Server publishing the managed tools
+    #[serde(default)]
+    pub server_url: Option<String>,
+    /// Agent token for toolbox requests; never written to disk
+    #[serde(skip)]
+    pub auth_token: Option<String>,
+    /// Seconds before a running tool is killed; 0 disables the timeout
+    #[serde(default = "default_execution_timeout_secs")]
+    pub execution_timeout_secs: u64,
+    /// Output kept per tool execution, in bytes
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// Whether tools may be run at all; server policy can turn this off
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Tools the server's policy allows; never written to disk
+    #[serde(skip)]
+    pub policy: ToolPolicy,
+    /// How tools that do not require admin rights are confined
+    #[serde(default)]
+    pub sandbox: ToolSandbox,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_execution_timeout_secs() -> u64 {
+    300
 }
 
+fn default_max_output_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Changes made by a server sync
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub added: Vec<Uuid>,
+    pub updated: Vec<Uuid>,
+    pub removed: Vec<Uuid>,
+    /// Tools that could not be installed, with the reason
+    pub failed: Vec<(Uuid, String)>,
+}
+
+/// Which tools index a tool is kept in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ToolIndex {
+    Local,
+    Server,
+}
+
+/// Tools by ID, as last read from or written to their indexes
+#[derive(Default)]
+struct ToolSets {
+    local: HashMap<Uuid, Tool>,
+    server: HashMap<Uuid, Tool>,
+}
+
+impl ToolSets {
+    fn get_mut(&mut self, index: ToolIndex) -> &mut HashMap<Uuid, Tool> {
+        match index {
+            ToolIndex::Local => &mut self.local,
+            ToolIndex::Server => &mut self.server,
+        }
+    }
+}
+
+/// The device's tools, shared as `Arc<ToolboxManager>` between the session
+/// window, the CLI and the background sync.
+///
+/// Every change is made to the index as it is on disk, under `INDEX_LOCK`
+/// and an advisory lock on the index file, so agents in other processes can
+/// change the toolbox too without either losing the other's tools.
 pub struct ToolboxManager {
     config: ToolboxConfig,
-    local_tools: HashMap<Uuid, Tool>,
-    server_tools: HashMap<Uuid, Tool>,
+    tools: RwLock<ToolSets>,
+    audit_log: parking_lot::Mutex<Vec<ToolAuditEntry>>,
+    /// Bumped whenever the tools change
+    changes: watch::Sender<u64>,
 }
 
 impl ToolboxManager {
     pub async fn new(config: ToolboxConfig) -> Result<Self> {
-        let mut manager = Self {
+        let manager = Self {
             config,
-            local_tools: HashMap::new(),
-            server_tools: HashMap::new(),
+            tools: RwLock::new(ToolSets::default()),
+            audit_log: parking_lot::Mutex::new(Vec::new()),
+            changes: watch::channel(0).0,
         };
         
         manager.initialize().await?;
         Ok(manager)
     }
     
-    async fn initialize(&mut self) -> Result<()> {
+    async fn initialize(&self) -> Result<()> {
         // Ensure local tools directory exists
         if !self.config.local_tools_path.exists() {
             fs::create_dir_all(&self.config.local_tools_path).await?;
             info!("Created local tools directory: {}", self.config.local_tools_path.display());
         }
         
-        // Load local tools
-        self.load_local_tools().await?;
+        self.reload().await?;
         
-        // Sync with server if enabled
+        // Sync with server if enabled; previously downloaded tools stay usable offline
         if self.config.server_sync_enabled {
-            self.sync_server_tools().await?;
+            match self.sync_server_tools().await {
+                Ok(report) => {
+                    for (tool_id, error) in &report.failed {
+                        warn!("Server tool {} not installed: {}", tool_id, error);
+                    }
+                }
+                Err(e) => warn!("Server tool synchronization failed: {}", e),
+            }
         }
         
         Ok(())
     }
     
-    async fn load_local_tools(&mut self) -> Result<()> {
-        let tools_config_path = self.config.local_tools_path.join("tools.json");
+    /// Re-read both tool indexes, picking up changes made by other processes
+    pub async fn reload(&self) -> Result<()> {
+        let local = read_index(&self.index_path(ToolIndex::Local)).await?;
+        let server = read_index(&self.index_path(ToolIndex::Server)).await?;
+        info!("Loaded {} local and {} server-managed tools", local.len(), server.len());
+        
+        *self.tools.write() = ToolSets { local, server };
+        self.notify();
+        Ok(())
+    }
+    
+    /// Receiver that is marked changed whenever tools are added, removed,
+    /// updated or reloaded
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+    
+    fn notify(&self) {
+        self.changes.send_modify(|generation| *generation += 1);
+    }
+    
+    fn index_path(&self, index: ToolIndex) -> PathBuf {
+        match index {
+            ToolIndex::Local => self.config.local_tools_path.join("tools.json"),
+            ToolIndex::Server => self.server_tools_dir().join("tools.json"),
+        }
+    }
+    
+    /// Apply `change` to an index as it is on disk, persist it and make it
+    /// this manager's view of that index
+    async fn update_index<R>(&self, index: ToolIndex, change: impl FnOnce(&mut HashMap<Uuid, Tool>) -> R) -> Result<R> {
+        let _guard = INDEX_LOCK.lock().await;
+        let path = self.index_path(index);
+        let _lock = IndexLock::acquire(&path).await?;
+        
+        let mut tools = read_index(&path).await?;
+        let result = change(&mut tools);
+        write_index(&path, &tools.values().collect::<Vec<_>>()).await?;
+        
+        *self.tools.write().get_mut(index) = tools;
+        self.notify();
+        Ok(result)
+    }
+    
+    fn server_sync(&self) -> Option<ServerSync> {
+        let server_url = self.config.server_url.clone()?;
+        Some(ServerSync::new(self.config.clone(), server_url))
+    }
+    
+    fn server_tools_dir(&self) -> PathBuf {
+        self.config.local_tools_path.join("server")
+    }
+    
+    /// Path of a server-managed tool's executable
+    fn server_tool_file(&self, tool: &Tool) -> Result<PathBuf> {
+        let program = tool.program();
+        let file_name = Path::new(&program)
+            .file_name()
+            .ok_or_else(|| GhostLinkError::Other(format!("Invalid command for tool {}: {}", tool.name, tool.command)))?;
+        Ok(self.server_tools_dir().join(tool.id.to_string()).join(file_name))
+    }
+    
+    /// Bring server-managed tools in line with the server's manifest.
+    ///
+    /// New tools are downloaded; installed tools are re-downloaded when the
+    /// server's version differs and the tool has `auto_update` set. Tools the
+    /// server no longer lists are removed. A tool that fails to download or
+    /// verify is reported in `failed` and keeps its previous installation.
+    pub async fn sync_server_tools(&self) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        let Some(sync) = self.server_sync() else {
+            debug!("No toolbox server configured, skipping sync");
+            return Ok(report);
+        };
+        
+        let _guard = INDEX_LOCK.lock().await;
+        let index_path = self.index_path(ToolIndex::Server);
+        let _lock = IndexLock::acquire(&index_path).await?;
+        let mut server_tools = read_index(&index_path).await?;
         
-        if tools_config_path.exists() {
-            let content = fs::read_to_string(&tools_config_path).await?;
-            let tools: Vec<Tool> = serde_json::from_str(&content)?;
+        let manifest = sync.fetch_manifest().await?;
+        let listed: HashSet<Uuid> = manifest.iter().map(|entry| entry.id).collect();
+        
+        for entry in manifest {
+            let existing = server_tools.get(&entry.id);
+            let auto_update = existing
+                .map(|tool| tool.auto_update)
+                .unwrap_or(self.config.auto_update_enabled);
             
-            for tool in tools {
-                self.local_tools.insert(tool.id, tool);
+            if let Some(existing) = existing {
+                let installed = self.server_tool_file(existing).map(|path| path.exists()).unwrap_or(false);
+                if installed && existing.version == entry.version {
+                    continue;
+                }
+                if installed && !(auto_update && self.config.auto_update_enabled) {
+                    debug!("Tool '{}' {} available, auto-update disabled", existing.name, entry.version);
+                    continue;
+                }
             }
             
-            info!("Loaded {} local tools", self.local_tools.len());
+            let is_update = existing.is_some();
+            let tool = entry.into_tool(auto_update);
+            match self.download_with(&sync, &tool).await {
+                Ok(()) => {
+                    if is_update {
+                        report.updated.push(tool.id);
+                    } else {
+                        report.added.push(tool.id);
+                    }
+                    server_tools.insert(tool.id, tool);
+                }
+                Err(e) => report.failed.push((tool.id, e.to_string())),
+            }
         }
         
-        Ok(())
+        let withdrawn: Vec<Uuid> = server_tools.keys()
+            .filter(|id| !listed.contains(id))
+            .copied()
+            .collect();
+        for tool_id in withdrawn {
+            server_tools.remove(&tool_id);
+            let tool_dir = self.server_tools_dir().join(tool_id.to_string());
+            if tool_dir.exists() {
+                fs::remove_dir_all(&tool_dir).await?;
+            }
+            report.removed.push(tool_id);
+        }
+        
+        write_index(&index_path, &server_tools.values().collect::<Vec<_>>()).await?;
+        self.tools.write().server = server_tools;
+        self.notify();
+        
+        info!(
+            "Synced server tools: {} added, {} updated, {} removed, {} failed",
+            report.added.len(), report.updated.len(), report.removed.len(), report.failed.len()
+        );
+        Ok(report)
     }
     
-    async fn sync_server_tools(&mut self) -> Result<()> {
-        // TODO: Implement server synchronization
-        debug!("Server tool synchronization not yet implemented");
-        Ok(())
+    /// Reload and sync server tools every `interval` until the manager is
+    /// dropped
+    pub fn start_background_sync(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(manager) = manager.upgrade() else {
+                    break;
+                };
+                // Also picks up local tools added by other processes
+                if let Err(e) = manager.reload().await {
+                    warn!("Failed to reload tools: {}", e);
+                }
+                if let Err(e) = manager.sync_server_tools().await {
+                    warn!("Server tool synchronization failed: {}", e);
+                }
+            }
+        })
     }
     
-    pub async fn add_tool(&mut self, tool: Tool) -> Result<()> {
+    pub async fn add_tool(&self, tool: Tool) -> Result<()> {
         // Download and verify tool if it's server-managed
         if tool.server_managed {
             self.download_tool(&tool).await?;
         }
         
-        // Add to appropriate collection
-        if tool.server_managed {
-            self.server_tools.insert(tool.id, tool);
-        } else {
-            self.local_tools.insert(tool.id, tool);
-            self.save_local_tools().await?;
-        }
-        
-        Ok(())
+        let index = if tool.server_managed { ToolIndex::Server } else { ToolIndex::Local };
+        self.update_index(index, |tools| {
+            tools.insert(tool.id, tool);
+        }).await
     }
     
-    pub fn remove_tool(&mut self, tool_id: &Uuid) -> Result<()> {
-        if self.local_tools.remove(tool_id).is_some() {
-            // Remove local tool files
-            let tool_dir = self.config.local_tools_path.join(tool_id.to_string());
-            if tool_dir.exists() {
-                std::fs::remove_dir_all(tool_dir)?;
-            }
-        } else if self.server_tools.remove(tool_id).is_some() {
-            // Remove server-managed tool files
-            let tool_dir = self.config.local_tools_path.join("server").join(tool_id.to_string());
-            if tool_dir.exists() {
-                std::fs::remove_dir_all(tool_dir)?;
+    pub async fn remove_tool(&self, tool_id: &Uuid) -> Result<()> {
+        // Checked on disk, as the tool may have been added by another process
+        for index in [ToolIndex::Local, ToolIndex::Server] {
+            if self.update_index(index, |tools| tools.remove(tool_id).is_some()).await? {
+                let tool_dir = match index {
+                    ToolIndex::Local => self.config.local_tools_path.join(tool_id.to_string()),
+                    ToolIndex::Server => self.server_tools_dir().join(tool_id.to_string()),
+                };
+                if tool_dir.exists() {
+                    fs::remove_dir_all(tool_dir).await?;
+                }
+                break;
             }
         }
         
         Ok(())
     }
     
-    pub fn get_tool(&self, tool_id: &Uuid) -> Option<&Tool> {
-        self.local_tools.get(tool_id)
-            .or_else(|| self.server_tools.get(tool_id))
+    pub fn get_tool(&self, tool_id: &Uuid) -> Option<Tool> {
+        let tools = self.tools.read();
+        tools.local.get(tool_id)
+            .or_else(|| tools.server.get(tool_id))
+            .cloned()
     }
     
-    pub fn list_tools(&self) -> Vec<&Tool> {
-        let mut tools: Vec<&Tool> = self.local_tools.values().collect();
-        tools.extend(self.server_tools.values());
-        tools.sort_by(|a, b| a.name.cmp(&b.name));
-        tools
+    pub fn list_tools(&self) -> Vec<Tool> {
+        let tools = self.tools.read();
+        let mut list: Vec<Tool> = tools.local.values().chain(tools.server.values()).cloned().collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
     }
     
-    pub fn list_tools_by_category(&self, category: &ToolCategory) -> Vec<&Tool> {
+    pub fn list_tools_by_category(&self, category: &ToolCategory) -> Vec<Tool> {
         self.list_tools()
             .into_iter()
             .filter(|tool| std::mem::discriminant(&tool.category) == std::mem::discriminant(category))
@@ -160,49 +468,260 @@ impl ToolboxManager {
     }
     
     async fn download_tool(&self, tool: &Tool) -> Result<()> {
-        // TODO: Implement tool download from server
-        debug!("Tool download not yet implemented for: {}", tool.name);
-        Ok(())
+        let sync = self.server_sync()
+            .ok_or_else(|| GhostLinkError::Other(format!("No toolbox server configured to download '{}'", tool.name)))?;
+        self.download_with(&sync, tool).await
     }
     
-    async fn save_local_tools(&self) -> Result<()> {
-        let tools: Vec<&Tool> = self.local_tools.values().collect();
-        let tools_config_path = self.config.local_tools_path.join("tools.json");
-        let content = serde_json::to_string_pretty(&tools)?;
-        fs::write(&tools_config_path, content).await?;
+    /// Download a tool next to its final path and move it into place only
+    /// once the SHA-256 matches, so a bad download never replaces a good one
+    async fn download_with(&self, sync: &ServerSync, tool: &Tool) -> Result<()> {
+        if tool.checksum.trim().is_empty() {
+            return Err(GhostLinkError::Other(format!("Server published no checksum for tool '{}'", tool.name)));
+        }
+        
+        let dest = self.server_tool_file(tool)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let partial = dest.with_extension("part");
+        
+        let size = sync.download_to_file(&sync.tool_download_url(&tool.id), &partial).await?;
+        
+        let actual = sha256_file(&partial).await?;
+        if !actual.eq_ignore_ascii_case(tool.checksum.trim()) {
+            fs::remove_file(&partial).await?;
+            return Err(GhostLinkError::Other(format!(
+                "Checksum mismatch for tool '{}': expected {}, got {}",
+                tool.name, tool.checksum, actual
+            )));
+        }
+        
+        fs::rename(&partial, &dest).await?;
+        
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o755)).await?;
+        }
+        
+        info!("Installed server tool '{}' {} ({} bytes)", tool.name, tool.version, size);
         Ok(())
     }
     
-    pub async fn execute_tool(&self, tool_id: &Uuid, args: Vec<String>) -> Result<String> {
+    /// Limits from the toolbox configuration
+    pub fn execution_limits(&self) -> ExecutionLimits {
+        ExecutionLimits {
+            timeout: match self.config.execution_timeout_secs {
+                0 => None,
+                secs => Some(std::time::Duration::from_secs(secs)),
+            },
+            max_output_bytes: self.config.max_output_bytes,
+        }
+    }
+    
+    /// Run a tool and wait for its stdout; fails if it exits non-zero
+    pub async fn execute_tool(
+        &self,
+        tool_id: &Uuid,
+        vars: &HashMap<String, String>,
+        args: Vec<String>,
+    ) -> Result<String> {
+        let output = self.execute_tool_streaming(tool_id, vars, args).await?.wait().await;
+        
+        let reason = match output.status {
+            ToolExitStatus::Exited(Some(0)) => return Ok(output.stdout),
+            ToolExitStatus::Exited(_) => output.stderr.trim_end().to_string(),
+            ToolExitStatus::TimedOut => "timed out".to_string(),
+            ToolExitStatus::Cancelled => "cancelled".to_string(),
+        };
+        let tool = self.get_tool(tool_id).map_or_else(|| tool_id.to_string(), |tool| tool.name);
+        Err(ToolError::ExecutionFailed { tool, reason }.into())
+    }
+    
+    /// Start a tool and stream its output, with the configured limits and
+    /// no elevation
+    pub async fn execute_tool_streaming(
+        &self,
+        tool_id: &Uuid,
+        vars: &HashMap<String, String>,
+        args: Vec<String>,
+    ) -> Result<ToolExecutionHandle> {
+        self.execute_tool_streaming_with(tool_id, vars, args, self.execution_limits(), None).await
+    }
+    
+    /// Start a tool with its template variables substituted; `args` are
+    /// appended after the templated arguments.
+    ///
+    /// The tool must pass the server's tool policy. A `requires_admin` tool
+    /// runs with `elevation`, the rights the PAM flow approved for the
+    /// session, and is refused without them; other tools run in the
+    /// configured sandbox. Each request is recorded in the audit log.
+    pub async fn execute_tool_streaming_with(
+        &self,
+        tool_id: &Uuid,
+        vars: &HashMap<String, String>,
+        args: Vec<String>,
+        limits: ExecutionLimits,
+        elevation: Option<&ElevatedContext>,
+    ) -> Result<ToolExecutionHandle> {
         let tool = self.get_tool(tool_id)
-            .ok_or_else(|| anyhow::anyhow!("Tool not found: {}", tool_id))?;
+            .ok_or_else(|| ToolError::NotFound { tool: tool_id.to_string() })?;
         
-        info!("Executing tool: {} with args: {:?}", tool.name, args);
+        let mut entry = ToolAuditEntry {
+            id: Uuid::new_v4(),
+            timestamp: chrono::Utc::now(),
+            tool_id: tool.id,
+            tool_name: tool.name.clone(),
+            checksum: None,
+            decision: ToolDecision::Allowed,
+            reason: None,
+            elevation: None,
+        };
+        let result = self.launch(&tool, vars, args, limits, elevation, &mut entry).await;
+        match &result {
+            Ok(_) => info!("Tool '{}' allowed", tool.name),
+            Err(e) => {
+                entry.decision = e.into();
+                entry.reason = Some(e.to_string());
+                warn!("{}", e);
+            }
+        }
         
-        // Build command
-        let mut command = tokio::process::Command::new(&tool.command);
-        command.args(&args);
+        self.record(entry);
+        Ok(result?)
+    }
+    
+    /// Run an inline script from a deployment and wait for it. Scripts are
+    /// held to the policy for custom tools by their checksum and never
+    /// elevated.
+    pub async fn run_script(&self, script: &queue::InlineScript, limits: ExecutionLimits) -> Result<ToolOutput> {
+        let name = format!("inline {:?} script", script.interpreter).to_lowercase();
+        let checksum = hex::encode(Sha256::digest(script.body.as_bytes()));
+        let mut entry = ToolAuditEntry {
+            id: Uuid::new_v4(),
+            timestamp: chrono::Utc::now(),
+            tool_id: Uuid::nil(),
+            tool_name: name.clone(),
+            checksum: Some(checksum.clone()),
+            decision: ToolDecision::Allowed,
+            reason: None,
+            elevation: None,
+        };
+        
+        let checked = if !self.config.enabled {
+            Err("tools are disabled on this device".to_string())
+        } else {
+            self.config.policy.check_script(&checksum)
+        };
+        if let Err(reason) = checked {
+            let e = ToolError::BlockedByPolicy { tool: name, reason };
+            entry.decision = (&e).into();
+            entry.reason = Some(e.to_string());
+            warn!("{}", e);
+            self.record(entry);
+            return Err(e.into());
+        }
+        self.record(entry);
+        
+        let (program, args, extension) = script.interpreter.command();
+        let dir = std::env::temp_dir().join("ghostlink-scripts").join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).await?;
+        let path = dir.join(format!("script.{}", extension));
+        fs::write(&path, &script.body).await?;
+        
+        let mut argv: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        argv.push(path.to_string_lossy().into_owned());
+        info!("Executing {} ({} bytes)", name, script.body.len());
+        let output = match execution::spawn_tool_in(Path::new(program), &argv, Some(&dir), limits, &self.config.sandbox) {
+            Ok(handle) => Ok(handle.wait().await),
+            Err(GhostLinkError::Io(io)) if io.kind() == std::io::ErrorKind::NotFound => {
+                Err(ToolError::ProgramMissing { tool: name, program: program.to_string() }.into())
+            }
+            Err(e) => Err(ToolError::ExecutionFailed { tool: name, reason: e.to_string() }.into()),
+        };
+        if let Err(e) = fs::remove_dir_all(&dir).await {
+            debug!("Failed to remove script directory {}: {}", dir.display(), e);
+        }
+        output
+    }
+    
+    fn record(&self, entry: ToolAuditEntry) {
+        let mut audit_log = self.audit_log.lock();
+        audit_log.push(entry);
+        if audit_log.len() > MAX_AUDIT_ENTRIES {
+            audit_log.remove(0);
+        }
+    }
+    
+    /// Snapshot of the tool audit log, oldest first
+    pub fn audit_log(&self) -> Vec<ToolAuditEntry> {
+        self.audit_log.lock().clone()
+    }
+    
+    async fn launch(
+        &self,
+        tool: &Tool,
+        vars: &HashMap<String, String>,
+        args: Vec<String>,
+        limits: ExecutionLimits,
+        elevation: Option<&ElevatedContext>,
+        entry: &mut ToolAuditEntry,
+    ) -> std::result::Result<ToolExecutionHandle, ToolError> {
+        let failed = |e: GhostLinkError| ToolError::ExecutionFailed { tool: tool.name.clone(), reason: e.to_string() };
+        if !self.config.enabled {
+            return Err(ToolError::BlockedByPolicy {
+                tool: tool.name.clone(),
+                reason: "tools are disabled on this device".to_string(),
+            });
+        }
+        
+        let mut argv = tool.command_line(vars).map_err(failed)?;
+        let program = argv.remove(0);
+        argv.extend(args);
         
         // Set working directory to tool's directory
         let tool_dir = if tool.server_managed {
-            self.config.local_tools_path.join("server").join(tool.id.to_string())
+            self.server_tools_dir().join(tool.id.to_string())
         } else {
             self.config.local_tools_path.join(tool.id.to_string())
         };
         
-        if tool_dir.exists() {
-            command.current_dir(&tool_dir);
-        }
+        // Prefer the copy installed in the tool's directory
+        let installed = tool_dir.join(&program);
+        let program = if installed.is_file() {
+            installed
+        } else {
+            PathBuf::from(program)
+        };
         
-        // Execute command
-        let output = command.output().await?;
+        if program.is_file() {
+            entry.checksum = Some(sha256_file(&program).await.map_err(failed)?);
+        }
+        self.config.policy.check(tool, entry.checksum.as_deref())
+            .map_err(|reason| ToolError::BlockedByPolicy { tool: tool.name.clone(), reason })?;
         
-        if output.status.success() {
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
-        } else {
-            let error = String::from_utf8_lossy(&output.stderr).to_string();
-            Err(anyhow::anyhow!("Tool execution failed: {}", error))
+        let working_dir = tool_dir.exists().then_some(tool_dir.as_path());
+        if tool.requires_admin {
+            let context = elevation.ok_or_else(|| ToolError::ElevationRequired {
+                tool: tool.name.clone(),
+                reason: "no elevation has been approved for this session".to_string(),
+            })?;
+            entry.elevation = Some(context.level.to_string());
+            let (launcher, argv) = context.wrap(&program.to_string_lossy(), &argv)
+                .map_err(|e| ToolError::ElevationRequired { tool: tool.name.clone(), reason: e.to_string() })?;
+            info!("Executing tool: {} as {} with args: {:?}", tool.name, context.level, argv);
+            return execution::spawn_tool(Path::new(&launcher), &argv, working_dir, limits).map_err(failed);
         }
+        
+        info!("Executing tool: {} with args: {:?}", tool.name, argv);
+        execution::spawn_tool_in(&program, &argv, working_dir, limits, &self.config.sandbox).map_err(|e| match e {
+            GhostLinkError::Io(io) if io.kind() == std::io::ErrorKind::NotFound => ToolError::ProgramMissing {
+                tool: tool.name.clone(),
+                program: program.display().to_string(),
+            },
+            e => failed(e),
+        })
     }
 }
 
@@ -217,6 +736,395 @@ impl Default for ToolboxConfig {
             auto_update_enabled: true,
             organization_id: None,
             user_id: None,
+            server_url: None,
+            auth_token: None,
+            execution_timeout_secs: default_execution_timeout_secs(),
+            max_output_bytes: default_max_output_bytes(),
+            enabled: true,
+            policy: ToolPolicy::default(),
+            sandbox: ToolSandbox::default(),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Advisory lock on a tools index, honoured by every agent process that
+/// changes it; released when dropped
+struct IndexLock(std::fs::File);
+
+impl IndexLock {
+    async fn acquire(index: &Path) -> Result<Self> {
+        if let Some(parent) = index.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let path = index.with_extension("json.lock");
+        let file = tokio::task::spawn_blocking(move || -> std::io::Result<std::fs::File> {
+            let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+            file.lock()?;
+            Ok(file)
+        }).await??;
+        Ok(Self(file))
+    }
+}
+
+/// Tools in an index, none if it has not been written yet
+async fn read_index(path: &Path) -> Result<HashMap<Uuid, Tool>> {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let tools: Vec<Tool> = serde_json::from_str(&content)?;
+    Ok(tools.into_iter().map(|tool| (tool.id, tool)).collect())
+}
+
+/// Write a tools index through a temporary file and rename
+async fn write_index(path: &Path, tools: &[&Tool]) -> Result<()> {
+    let content = serde_json::to_string_pretty(tools)?;
+    let tmp_path = path.with_extension(format!("json.{}.tmp", Uuid::new_v4()));
+    fs::write(&tmp_path, content).await?;
+    if let Err(e) = fs::rename(&tmp_path, path).await {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Lowercase hex SHA-256 of a file
+async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn checksum(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    async fn publish(server: &MockServer, tool_id: Uuid, version: &str, data: &[u8], published_checksum: &str) {
+        server.reset().await;
+        let manifest = serde_json::json!({
+            "Network Tools": [{
+                "id": tool_id,
+                "name": "PortScan",
+                "description": "Scan ports",
+                "category": "Network Tools",
+                "version": version,
+                "checksum": published_checksum,
+                "file_path": "tools/network/portscan.exe",
+                "file_size": data.len(),
+                "permissions": { "requires_admin": false }
+            }]
+        });
+        Mock::given(method("GET"))
+            .and(path("/api/toolbox/available"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(manifest))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/api/toolbox/download/{}", tool_id)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(data.to_vec()))
+            .mount(server)
+            .await;
+    }
+
+    async fn manager(server: &MockServer, dir: &Path) -> ToolboxManager {
+        ToolboxManager::new(ToolboxConfig {
+            local_tools_path: dir.to_path_buf(),
+            server_sync_enabled: false,
+            auto_update_enabled: true,
+            organization_id: None,
+            user_id: None,
+            server_url: Some(server.uri()),
+            auth_token: Some("agent-token".to_string()),
+            ..ToolboxConfig::default()
+        }).await.unwrap()
+    }
+
+    fn installed_file(dir: &Path, tool_id: Uuid) -> PathBuf {
+        dir.join("server").join(tool_id.to_string()).join("portscan.exe")
+    }
+
+    #[test]
+    fn test_tools_json_without_args_template() {
+        let json = serde_json::json!([{
+            "id": Uuid::new_v4(),
+            "name": "Disk Usage",
+            "description": "Show disk usage",
+            "command": "C:\\Program Files\\du.exe",
+            "icon_path": null,
+            "category": "System",
+            "version": "1.0.0",
+            "checksum": "manual",
+            "is_portable": true,
+            "requires_admin": false,
+            "auto_update": false,
+            "server_managed": false
+        }]);
+
+        let tools: Vec<Tool> = serde_json::from_value(json).unwrap();
+        assert!(tools[0].args_template.is_empty());
+        assert_eq!(tools[0].command_line(&HashMap::new()).unwrap(), vec!["C:\\Program Files\\du.exe"]);
+        assert!(tools[0].command_line(&HashMap::from([("x".to_string(), "1".to_string())])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_downloads_new_tool() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let tool_id = Uuid::new_v4();
+        let data = b"portscan v1".to_vec();
+        publish(&server, tool_id, "1.0", &data, &checksum(&data)).await;
+        // The first download attempt hits a transient server error
+        Mock::given(method("GET"))
+            .and(path(format!("/api/toolbox/download/{}", tool_id)))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        let toolbox = manager(&server, dir.path()).await;
+        let report = toolbox.sync_server_tools().await.unwrap();
+
+        assert_eq!(report.added, vec![tool_id]);
+        assert_eq!(std::fs::read(installed_file(dir.path(), tool_id)).unwrap(), data);
+        assert!(matches!(toolbox.get_tool(&tool_id).unwrap().category, ToolCategory::Network));
+
+        // The index survives a restart
+        let reloaded = manager(&server, dir.path()).await;
+        assert_eq!(reloaded.get_tool(&tool_id).unwrap().version, "1.0");
+    }
+
+    #[tokio::test]
+    async fn test_sync_updates_changed_version() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let tool_id = Uuid::new_v4();
+        let v1 = b"portscan v1".to_vec();
+        publish(&server, tool_id, "1.0", &v1, &checksum(&v1)).await;
+
+        let toolbox = manager(&server, dir.path()).await;
+        toolbox.sync_server_tools().await.unwrap();
+
+        let v2 = b"portscan v2".to_vec();
+        publish(&server, tool_id, "2.0", &v2, &checksum(&v2)).await;
+        let report = toolbox.sync_server_tools().await.unwrap();
+        assert_eq!(report.updated, vec![tool_id]);
+        assert_eq!(std::fs::read(installed_file(dir.path(), tool_id)).unwrap(), v2);
+        assert_eq!(toolbox.get_tool(&tool_id).unwrap().version, "2.0");
+
+        // Without auto_update the installed version is kept
+        toolbox.update_index(ToolIndex::Server, |tools| tools.get_mut(&tool_id).unwrap().auto_update = false).await.unwrap();
+        let v3 = b"portscan v3".to_vec();
+        publish(&server, tool_id, "3.0", &v3, &checksum(&v3)).await;
+        let report = toolbox.sync_server_tools().await.unwrap();
+        assert!(report.updated.is_empty());
+        assert_eq!(std::fs::read(installed_file(dir.path(), tool_id)).unwrap(), v2);
+    }
+
+    fn local_tool(name: &str, command: &str) -> Tool {
+        Tool {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            description: format!("{} tool", name),
+            command: command.to_string(),
+            icon_path: None,
+            category: ToolCategory::System,
+            version: "1.0".to_string(),
+            checksum: "manual".to_string(),
+            is_portable: true,
+            requires_admin: false,
+            auto_update: false,
+            server_managed: false,
+            args_template: Vec::new(),
+        }
+    }
+
+    /// A local tool whose executable is a shell script in its directory
+    #[cfg(unix)]
+    async fn script_tool(toolbox: &ToolboxManager, dir: &Path, requires_admin: bool) -> (Uuid, String) {
+        use std::os::unix::fs::PermissionsExt;
+        let tool = Tool { requires_admin, ..local_tool("Report", "report.sh") };
+        let tool_id = tool.id;
+        let script = b"#!/bin/sh\necho ran\n";
+        let file = dir.join(tool_id.to_string()).join("report.sh");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, script).unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+        toolbox.add_tool(tool).await.unwrap();
+        (tool_id, checksum(script))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_tool_policy_is_enforced_and_audited() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut toolbox = manager(&server, dir.path()).await;
+        let (tool_id, script_checksum) = script_tool(&toolbox, dir.path(), false).await;
+        let vars = HashMap::new();
+
+        toolbox.config.policy = ToolPolicy { allowed_checksums: Some(vec![checksum(b"other")]), ..Default::default() };
+        let error = toolbox.execute_tool(&tool_id, &vars, Vec::new()).await.unwrap_err();
+        assert!(matches!(error, GhostLinkError::Toolbox(ToolError::BlockedByPolicy { .. })));
+
+        toolbox.config.policy = ToolPolicy { allowed_checksums: Some(vec![script_checksum.clone()]), ..Default::default() };
+        assert_eq!(toolbox.execute_tool(&tool_id, &vars, Vec::new()).await.unwrap(), "ran\n");
+
+        let audit = toolbox.audit_log();
+        assert_eq!(audit.iter().map(|entry| entry.decision).collect::<Vec<_>>(), [ToolDecision::BlockedByPolicy, ToolDecision::Allowed]);
+        assert_eq!(audit[1].checksum.as_deref(), Some(script_checksum.as_str()));
+        assert!(audit[0].reason.as_deref().unwrap().contains("not on the allowed list"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_admin_tools_need_an_approved_elevation() {
+        use crate::elevation::{ElevationLevel, ElevationMethod};
+
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let toolbox = manager(&server, dir.path()).await;
+        let (tool_id, _) = script_tool(&toolbox, dir.path(), true).await;
+        let vars = HashMap::new();
+
+        let error = toolbox.execute_tool(&tool_id, &vars, Vec::new()).await.unwrap_err();
+        assert!(matches!(error, GhostLinkError::Toolbox(ToolError::ElevationRequired { .. })));
+
+        // The elevation's rights are already held by the agent, so the tool runs as-is
+        let elevation = ElevatedContext::new(ElevationLevel::RunAsAdmin, ElevationMethod::Inherited);
+        let limits = toolbox.execution_limits();
+        let handle = toolbox.execute_tool_streaming_with(&tool_id, &vars, Vec::new(), limits, Some(&elevation)).await.unwrap();
+        assert_eq!(handle.wait().await.stdout, "ran\n");
+
+        let audit = toolbox.audit_log();
+        assert_eq!(audit[0].decision, ToolDecision::ElevationRequired);
+        assert_eq!((audit[1].decision, audit[1].elevation.as_deref()), (ToolDecision::Allowed, Some("administrator")));
+    }
+
+    #[tokio::test]
+    async fn test_missing_tools_are_told_apart() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let toolbox = manager(&server, dir.path()).await;
+        let vars = HashMap::new();
+
+        let error = toolbox.execute_tool(&Uuid::new_v4(), &vars, Vec::new()).await.unwrap_err();
+        assert!(matches!(error, GhostLinkError::Toolbox(ToolError::NotFound { .. })));
+        assert_eq!(error.exit_code(), 66);
+
+        // Listed, but its executable was never downloaded
+        let tool = local_tool("Cleanup", "cleanup.exe");
+        let tool_id = tool.id;
+        toolbox.add_tool(tool).await.unwrap();
+        let error = toolbox.execute_tool(&tool_id, &vars, Vec::new()).await.unwrap_err();
+        assert!(
+            matches!(&error, GhostLinkError::Toolbox(ToolError::ProgramMissing { tool, .. }) if tool == "Cleanup"),
+            "{}",
+            error
+        );
+        assert_eq!(toolbox.audit_log()[0].decision, ToolDecision::ExecutionFailed);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch_discards_download() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let tool_id = Uuid::new_v4();
+        publish(&server, tool_id, "1.0", b"tampered", &checksum(b"original")).await;
+
+        let toolbox = manager(&server, dir.path()).await;
+        let report = toolbox.sync_server_tools().await.unwrap();
+
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].1.contains("Checksum mismatch"));
+        assert!(toolbox.get_tool(&tool_id).is_none());
+        let file = installed_file(dir.path(), tool_id);
+        assert!(!file.exists());
+        assert!(!file.with_extension("part").exists());
+    }
+
+    fn persisted_ids(index: &Path) -> HashSet<Uuid> {
+        let tools: Vec<Tool> = serde_json::from_str(&std::fs::read_to_string(index).unwrap()).unwrap();
+        tools.into_iter().map(|tool| tool.id).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_changes_keep_index_and_memory_in_step() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let toolbox = Arc::new(manager(&server, dir.path()).await);
+        let mut changes = toolbox.subscribe();
+        changes.mark_unchanged();
+
+        let tasks: Vec<_> = (0..16).map(|i| {
+            let toolbox = Arc::clone(&toolbox);
+            tokio::spawn(async move {
+                let tool = local_tool(&format!("Tool {}", i), "tool.sh");
+                let tool_id = tool.id;
+                toolbox.add_tool(tool).await.unwrap();
+                if i % 2 == 0 {
+                    toolbox.remove_tool(&tool_id).await.unwrap();
+                    None
+                } else {
+                    Some(tool_id)
+                }
+            })
+        }).collect();
+        let mut kept = HashSet::new();
+        for task in tasks {
+            kept.extend(task.await.unwrap());
+        }
+
+        let in_memory: HashSet<Uuid> = toolbox.list_tools().into_iter().map(|tool| tool.id).collect();
+        assert_eq!(in_memory.len(), 8);
+        assert_eq!(in_memory, kept);
+        assert_eq!(persisted_ids(&dir.path().join("tools.json")), in_memory);
+        assert!(changes.has_changed().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_managers_sharing_a_directory_keep_each_others_tools() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        // As if the session window and the CLI ran in separate processes
+        let window = Arc::new(manager(&server, dir.path()).await);
+        let cli = Arc::new(manager(&server, dir.path()).await);
+
+        let tasks: Vec<_> = (0..8).map(|i| {
+            let toolbox = Arc::clone(if i % 2 == 0 { &window } else { &cli });
+            tokio::spawn(async move { toolbox.add_tool(local_tool(&format!("Tool {}", i), "tool.sh")).await.unwrap() })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let persisted = persisted_ids(&dir.path().join("tools.json"));
+        assert_eq!(persisted.len(), 8);
+        // The window removes a tool only the CLI has seen
+        let late = local_tool("Late", "tool.sh");
+        let late_id = late.id;
+        cli.add_tool(late).await.unwrap();
+        assert!(window.get_tool(&late_id).is_none());
+        window.remove_tool(&late_id).await.unwrap();
+        assert_eq!(persisted_ids(&dir.path().join("tools.json")), persisted);
+
+        cli.reload().await.unwrap();
+        let listed = |toolbox: &ToolboxManager| toolbox.list_tools().into_iter().map(|tool| tool.id).collect::<HashSet<_>>();
+        assert_eq!(listed(&window), listed(&cli));
+        assert_eq!(listed(&cli), persisted_ids(&dir.path().join("tools.json")));
+    }
+}
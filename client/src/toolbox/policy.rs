@@ -0,0 +1,158 @@
+//! Which tools may run on this device
+//!
+//! The server's policy can limit tools to a list of SHA-256 checksums and to
+//! a list of categories; each list it sets must match. The checksum is taken
+//! from the installed executable rather than the tool's declared checksum,
+//! so a replaced binary is caught. Inline scripts from deployments count as
+//! `custom` tools, checked by the checksum of their text. Every launch
+//! decision is kept in the toolbox audit log.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{Tool, ToolCategory};
+use crate::error::ToolError;
+
+/// Tools the server allows; unset lists allow anything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolPolicy {
+    /// Lowercase hex SHA-256 of executables that may run
+    #[serde(default)]
+    pub allowed_checksums: Option<Vec<String>>,
+    /// Category names that may run, e.g. `network`
+    #[serde(default)]
+    pub allowed_categories: Option<Vec<String>>,
+}
+
+impl ToolPolicy {
+    /// Check `tool` against the policy; `checksum` is that of its installed
+    /// executable, if there is one to hash
+    pub fn check(&self, tool: &Tool, checksum: Option<&str>) -> Result<(), String> {
+        self.check_category(tool.category.name())?;
+        if let Some(checksums) = &self.allowed_checksums {
+            let Some(checksum) = checksum else {
+                return Err("its executable is not installed in the toolbox, so its checksum cannot be checked".to_string());
+            };
+            check_checksum(checksums, checksum)?;
+        }
+        Ok(())
+    }
+
+    /// Check an inline script from a deployment, which counts as a `custom`
+    /// tool; `checksum` is that of the script's text
+    pub fn check_script(&self, checksum: &str) -> Result<(), String> {
+        self.check_category(ToolCategory::Custom.name())?;
+        match &self.allowed_checksums {
+            Some(checksums) => check_checksum(checksums, checksum),
+            None => Ok(()),
+        }
+    }
+
+    fn check_category(&self, category: &str) -> Result<(), String> {
+        if let Some(categories) = &self.allowed_categories {
+            if !categories.iter().any(|allowed| allowed.eq_ignore_ascii_case(category)) {
+                return Err(format!("{} tools are not allowed on this device", category));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_checksum(checksums: &[String], checksum: &str) -> Result<(), String> {
+    if !checksums.iter().any(|allowed| allowed.eq_ignore_ascii_case(checksum)) {
+        return Err(format!("checksum {} is not on the allowed list", checksum));
+    }
+    Ok(())
+}
+
+/// What became of a request to run a tool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolDecision {
+    Allowed,
+    BlockedByPolicy,
+    ElevationRequired,
+    ExecutionFailed,
+}
+
+impl From<&ToolError> for ToolDecision {
+    fn from(error: &ToolError) -> Self {
+        match error {
+            ToolError::BlockedByPolicy { .. } => ToolDecision::BlockedByPolicy,
+            ToolError::ElevationRequired { .. } => ToolDecision::ElevationRequired,
+            ToolError::NotFound { .. } | ToolError::ProgramMissing { .. } | ToolError::ExecutionFailed { .. } => {
+                ToolDecision::ExecutionFailed
+            }
+        }
+    }
+}
+
+/// Audit record of a request to run a tool
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolAuditEntry {
+    pub id: Uuid,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub tool_id: Uuid,
+    pub tool_name: String,
+    /// SHA-256 of the executable that was checked
+    pub checksum: Option<String>,
+    pub decision: ToolDecision,
+    pub reason: Option<String>,
+    /// Rights an admin tool ran with
+    pub elevation: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool(category: ToolCategory) -> Tool {
+        Tool {
+            id: Uuid::new_v4(),
+            name: "PortScan".to_string(),
+            description: "Scan ports".to_string(),
+            command: "portscan".to_string(),
+            icon_path: None,
+            category,
+            version: "1.0".to_string(),
+            checksum: "manual".to_string(),
+            is_portable: true,
+            requires_admin: false,
+            auto_update: false,
+            server_managed: false,
+            args_template: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_policy_evaluation() {
+        let network = tool(ToolCategory::Network);
+        assert!(ToolPolicy::default().check(&network, None).is_ok());
+
+        let by_category = ToolPolicy { allowed_categories: Some(vec!["Network".to_string()]), ..Default::default() };
+        assert!(by_category.check(&network, None).is_ok());
+        let denied = by_category.check(&tool(ToolCategory::Security), None).unwrap_err();
+        assert_eq!(denied, "security tools are not allowed on this device");
+
+        let pinned = ToolPolicy {
+            allowed_checksums: Some(vec!["ab12".to_string()]),
+            allowed_categories: Some(vec!["network".to_string()]),
+        };
+        assert!(pinned.check(&network, Some("AB12")).is_ok());
+        assert!(pinned.check(&network, Some("cd34")).is_err());
+        // A declared checksum is not enough; the executable must be hashed
+        assert!(pinned.check(&network, None).is_err());
+        assert!(pinned.check(&tool(ToolCategory::System), Some("ab12")).is_err());
+    }
+
+    #[test]
+    fn test_scripts_are_custom_tools() {
+        assert!(ToolPolicy::default().check_script("ab12").is_ok());
+        let network_only = ToolPolicy { allowed_categories: Some(vec!["network".to_string()]), ..Default::default() };
+        assert_eq!(network_only.check_script("ab12").unwrap_err(), "custom tools are not allowed on this device");
+
+        let pinned = ToolPolicy { allowed_checksums: Some(vec!["AB12".to_string()]), ..Default::default() };
+        assert!(pinned.check_script("ab12").is_ok());
+        assert!(pinned.check_script("cd34").is_err());
+    }
+}
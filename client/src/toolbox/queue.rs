@@ -0,0 +1,332 @@
+//! Commands from server deployments, kept until the server has their result
+//!
+//! A deployment's `ExecuteTool` is stored before it runs and its result
+//! after, in `tool-queue.json` next to the config, so neither is lost when
+//! the agent restarts or the link drops. The server sends a command again on
+//! every connect until it hears the result, so one already in the queue is
+//! not run twice. A command still marked running at start was cut short by
+//! the restart; it is reported as failed rather than run again, as scripts
+//! are rarely safe to repeat from the top.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::execution::ToolExitStatus;
+use super::{ToolboxConfig, ToolboxManager};
+use crate::config::ClientConfig;
+use crate::error::{GhostLinkError, ToolError};
+
+/// How an inline script is run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptInterpreter {
+    PowerShell,
+    Cmd,
+    Bash,
+    Sh,
+    Python,
+}
+
+impl ScriptInterpreter {
+    /// Program, the arguments before the script path, and the extension the
+    /// script is saved with
+    pub fn command(self) -> (&'static str, &'static [&'static str], &'static str) {
+        match self {
+            ScriptInterpreter::PowerShell if cfg!(windows) => {
+                ("powershell", &["-NoProfile", "-NonInteractive", "-ExecutionPolicy", "Bypass", "-File"], "ps1")
+            }
+            ScriptInterpreter::PowerShell => ("pwsh", &["-NoProfile", "-NonInteractive", "-File"], "ps1"),
+            ScriptInterpreter::Cmd => ("cmd", &["/C"], "cmd"),
+            ScriptInterpreter::Bash => ("bash", &[], "sh"),
+            ScriptInterpreter::Sh => ("sh", &[], "sh"),
+            ScriptInterpreter::Python if cfg!(windows) => ("python", &[], "py"),
+            ScriptInterpreter::Python => ("python3", &[], "py"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InlineScript {
+    pub interpreter: ScriptInterpreter,
+    pub body: String,
+}
+
+/// A tool or script to run for a deployment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolJob {
+    pub execution_id: Uuid,
+    pub deployment_id: Uuid,
+    #[serde(default)]
+    pub tool_id: Option<Uuid>,
+    #[serde(default)]
+    pub script: Option<InlineScript>,
+    /// Template variables of the tool
+    #[serde(default)]
+    pub parameters: HashMap<String, String>,
+    pub timeout_secs: u64,
+    pub max_output_bytes: usize,
+}
+
+/// How a job ended, in the server's terms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolOutcome {
+    Succeeded,
+    Failed,
+    TimedOut,
+    /// The tool policy refused it, or it needs rights a deployment cannot get
+    Blocked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolJobResult {
+    pub status: ToolOutcome,
+    pub exit_code: Option<i32>,
+    /// Stdout, then stderr
+    pub output: String,
+    pub truncated: bool,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+impl ToolJobResult {
+    fn failed(status: ToolOutcome, error: String) -> Self {
+        Self { status, exit_code: None, output: String::new(), truncated: false, duration_ms: 0, error: Some(error) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Waiting,
+    Running,
+    Done,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedJob {
+    job: ToolJob,
+    state: JobState,
+    #[serde(default)]
+    result: Option<ToolJobResult>,
+}
+
+/// Deployment jobs by arrival, until the server accepts their result
+pub struct CommandQueue {
+    /// Unset for agents that keep nothing between runs
+    path: Option<PathBuf>,
+    jobs: parking_lot::Mutex<Vec<QueuedJob>>,
+}
+
+impl CommandQueue {
+    /// A queue that is gone when the agent exits
+    pub fn in_memory() -> Self {
+        Self { path: None, jobs: parking_lot::Mutex::new(Vec::new()) }
+    }
+
+    /// The queue kept at `path`, starting from what is stored there
+    pub fn open(path: PathBuf) -> Self {
+        let mut jobs: Vec<QueuedJob> = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable command queue {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        for queued in jobs.iter_mut().filter(|queued| queued.state == JobState::Running) {
+            warn!("Execution {} was interrupted by the agent stopping", queued.job.execution_id);
+            queued.state = JobState::Done;
+            queued.result = Some(ToolJobResult::failed(ToolOutcome::Failed, "interrupted by the agent restarting".to_string()));
+        }
+        if !jobs.is_empty() {
+            info!("Loaded {} queued commands from {}", jobs.len(), path.display());
+        }
+        let queue = Self { path: Some(path), jobs: parking_lot::Mutex::new(jobs) };
+        queue.save(&queue.jobs.lock());
+        queue
+    }
+
+    /// `tool-queue.json` next to the default config
+    pub fn default_path() -> PathBuf {
+        ClientConfig::default_path().with_file_name("tool-queue.json")
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Store a job to run; false if it is already here
+    pub fn enqueue(&self, job: ToolJob) -> bool {
+        let mut jobs = self.jobs.lock();
+        if jobs.iter().any(|queued| queued.job.execution_id == job.execution_id) {
+            return false;
+        }
+        jobs.push(QueuedJob { job, state: JobState::Waiting, result: None });
+        self.save(&jobs);
+        true
+    }
+
+    /// The oldest job waiting to run, marked running
+    pub fn start_next(&self) -> Option<ToolJob> {
+        let mut jobs = self.jobs.lock();
+        let queued = jobs.iter_mut().find(|queued| queued.state == JobState::Waiting)?;
+        queued.state = JobState::Running;
+        let job = queued.job.clone();
+        self.save(&jobs);
+        Some(job)
+    }
+
+    pub fn finish(&self, execution_id: Uuid, result: ToolJobResult) {
+        let mut jobs = self.jobs.lock();
+        if let Some(queued) = jobs.iter_mut().find(|queued| queued.job.execution_id == execution_id) {
+            queued.state = JobState::Done;
+            queued.result = Some(result);
+            self.save(&jobs);
+        }
+    }
+
+    /// Results the server has not accepted yet, with their execution and
+    /// deployment
+    pub fn unreported(&self) -> Vec<(Uuid, Uuid, ToolJobResult)> {
+        self.jobs
+            .lock()
+            .iter()
+            .filter_map(|queued| Some((queued.job.execution_id, queued.job.deployment_id, queued.result.clone()?)))
+            .collect()
+    }
+
+    /// Drop a job the server has the result of
+    pub fn acknowledge(&self, execution_id: Uuid) {
+        let mut jobs = self.jobs.lock();
+        let before = jobs.len();
+        jobs.retain(|queued| queued.job.execution_id != execution_id || queued.state != JobState::Done);
+        if jobs.len() != before {
+            self.save(&jobs);
+        }
+    }
+
+    /// Write the queue through a rename, so a crash never leaves half a file
+    fn save(&self, jobs: &[QueuedJob]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let write = || -> std::io::Result<()> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let partial = path.with_extension("json.tmp");
+            std::fs::write(&partial, serde_json::to_vec(jobs)?)?;
+            std::fs::rename(&partial, path)
+        };
+        if let Err(e) = write() {
+            warn!("Failed to store command queue {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Run `job` with the toolbox `config` describes, which carries the
+/// server's tool policy. Deployments have no PAM approval behind them, so
+/// tools that require admin rights are refused.
+pub async fn execute(job: &ToolJob, mut config: ToolboxConfig) -> ToolJobResult {
+    config.server_sync_enabled = false;
+    config.execution_timeout_secs = job.timeout_secs;
+    config.max_output_bytes = job.max_output_bytes;
+    let toolbox = match ToolboxManager::new(config).await {
+        Ok(toolbox) => toolbox,
+        Err(e) => return ToolJobResult::failed(ToolOutcome::Failed, format!("Toolbox unavailable: {}", e)),
+    };
+    let limits = toolbox.execution_limits();
+
+    let started = Instant::now();
+    let output = match (&job.tool_id, &job.script) {
+        (Some(tool_id), _) => match toolbox.execute_tool_streaming_with(tool_id, &job.parameters, Vec::new(), limits, None).await {
+            Ok(handle) => Ok(handle.wait().await),
+            Err(e) => Err(e),
+        },
+        (None, Some(script)) => toolbox.run_script(script, limits).await,
+        (None, None) => return ToolJobResult::failed(ToolOutcome::Failed, "Nothing to run".to_string()),
+    };
+    let output = match output {
+        Ok(output) => output,
+        Err(GhostLinkError::Toolbox(e @ (ToolError::BlockedByPolicy { .. } | ToolError::ElevationRequired { .. }))) => {
+            return ToolJobResult::failed(ToolOutcome::Blocked, e.to_string());
+        }
+        Err(e) => return ToolJobResult::failed(ToolOutcome::Failed, e.to_string()),
+    };
+
+    let (status, exit_code, error) = match output.status {
+        ToolExitStatus::Exited(Some(0)) => (ToolOutcome::Succeeded, Some(0), None),
+        ToolExitStatus::Exited(code) => (ToolOutcome::Failed, code, None),
+        ToolExitStatus::TimedOut => (ToolOutcome::TimedOut, None, Some(format!("Timed out after {}s", job.timeout_secs))),
+        ToolExitStatus::Cancelled => (ToolOutcome::Failed, None, Some("Cancelled".to_string())),
+    };
+    let mut text = output.stdout;
+    if !output.stderr.is_empty() {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(&output.stderr);
+    }
+    ToolJobResult {
+        status,
+        exit_code,
+        output: text,
+        truncated: output.truncated,
+        duration_ms: started.elapsed().as_millis() as u64,
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job() -> ToolJob {
+        ToolJob {
+            execution_id: Uuid::new_v4(),
+            deployment_id: Uuid::new_v4(),
+            tool_id: None,
+            script: Some(InlineScript { interpreter: ScriptInterpreter::Sh, body: "echo hi".to_string() }),
+            parameters: HashMap::new(),
+            timeout_secs: 60,
+            max_output_bytes: 1024,
+        }
+    }
+
+    #[test]
+    fn test_queued_jobs_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tool-queue.json");
+        let queue = CommandQueue::open(path.clone());
+        let (first, second) = (job(), job());
+        assert!(queue.enqueue(first.clone()));
+        assert!(queue.enqueue(second.clone()));
+        assert!(!queue.enqueue(first.clone()), "a command sent again is not run twice");
+        assert_eq!(queue.start_next(), Some(first.clone()));
+        drop(queue);
+
+        // The running job was cut short; the waiting one still runs
+        let queue = CommandQueue::open(path.clone());
+        let unreported = queue.unreported();
+        assert_eq!(unreported.len(), 1);
+        assert_eq!((unreported[0].0, unreported[0].2.status), (first.execution_id, ToolOutcome::Failed));
+        assert_eq!(queue.start_next(), Some(second.clone()));
+        assert_eq!(queue.start_next(), None);
+
+        let result = ToolJobResult { status: ToolOutcome::Succeeded, exit_code: Some(0), output: "hi\n".to_string(), truncated: false, duration_ms: 4, error: None };
+        queue.finish(second.execution_id, result.clone());
+        queue.acknowledge(first.execution_id);
+        drop(queue);
+
+        let queue = CommandQueue::open(path);
+        assert_eq!(queue.unreported(), vec![(second.execution_id, second.deployment_id, result)]);
+        assert!(!queue.enqueue(second.clone()), "a result the server has not accepted is kept");
+        queue.acknowledge(second.execution_id);
+        assert!(queue.unreported().is_empty());
+        assert!(queue.enqueue(second), "accepted jobs are forgotten");
+    }
+}
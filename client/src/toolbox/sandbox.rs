@@ -0,0 +1,249 @@
+//! Confinement for tools that need no admin rights
+//!
+//! Off by default. `user` runs tools as a dedicated unprivileged account on
+//! Unix, which needs the agent to run as root. `restricted` applies a
+//! Landlock ruleset on Linux that lets the tool write only beneath its own
+//! directory; a kernel without Landlock refuses the tool rather than run it
+//! unconfined. Other platforms refuse `restricted` for now.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::process::Command;
+
+use crate::error::{GhostLinkError, Result};
+
+/// How tools that do not require admin rights are run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ToolSandbox {
+    /// With the agent's own rights
+    #[default]
+    None,
+    /// As this unprivileged account
+    User { name: String },
+    /// Writes limited to the tool's directory
+    Restricted,
+}
+
+impl ToolSandbox {
+    /// Set up `command`, which runs a tool installed in `tool_dir`, to be
+    /// confined once spawned
+    pub fn apply(&self, command: &mut Command, tool_dir: Option<&Path>) -> Result<()> {
+        match self {
+            ToolSandbox::None => Ok(()),
+            ToolSandbox::User { name } => run_as(command, name),
+            ToolSandbox::Restricted => restrict_writes(command, tool_dir),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn run_as(command: &mut Command, name: &str) -> Result<()> {
+    let (uid, gid) = lookup_account(name)?;
+    if uid == 0 {
+        return Err(GhostLinkError::Other(format!("Refusing to sandbox tools as {}, a superuser account", name)));
+    }
+    command.uid(uid).gid(gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_as(_command: &mut Command, name: &str) -> Result<()> {
+    return Err(GhostLinkError::Other(format!("Running tools as {} is not supported on this platform", name)))
+}
+
+/// User and group ID of the account `name`
+#[cfg(unix)]
+fn lookup_account(name: &str) -> Result<(u32, u32)> {
+    let c_name = std::ffi::CString::new(name)
+        .map_err(|_| GhostLinkError::Other(format!("Invalid account name {:?}", name)))?;
+    // SAFETY: passwd is plain data, filled in by getpwnam_r from `buffer`
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    let rc = unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut found)
+    };
+    if rc != 0 || found.is_null() {
+        return Err(GhostLinkError::Other(format!("No account named {} to run tools as", name)));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+#[cfg(target_os = "linux")]
+fn restrict_writes(command: &mut Command, tool_dir: Option<&Path>) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let abi = landlock::abi_version()
+        .map_err(|e| GhostLinkError::Other(format!("Landlock is not available to confine the tool: {}", e)))?;
+    let access = landlock::write_access(abi);
+    // Opened here so the child only makes system calls before exec
+    let dir = tool_dir.map(std::fs::File::open).transpose()?;
+    // SAFETY: the closure only makes Landlock and prctl system calls
+    unsafe {
+        command.pre_exec(move || landlock::restrict_self(access, dir.as_ref().map(|dir| dir.as_raw_fd())));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restrict_writes(_command: &mut Command, _tool_dir: Option<&Path>) -> Result<()> {
+    return Err(GhostLinkError::Other("Restricted tool sandboxing is not supported on this platform".to_string()))
+}
+
+/// The Landlock system calls, which libc names but does not wrap
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::io;
+    use std::os::fd::RawFd;
+
+    const CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// The kernel's Landlock ABI version
+    pub fn abi_version() -> io::Result<i64> {
+        // SAFETY: a null attribute with size 0 only queries the version
+        let version = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0usize,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if version < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(version)
+    }
+
+    /// Every kind of write the ABI can restrict
+    pub fn write_access(abi: i64) -> u64 {
+        let mut access = ACCESS_FS_WRITE_FILE
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_CHAR
+            | ACCESS_FS_MAKE_DIR
+            | ACCESS_FS_MAKE_REG
+            | ACCESS_FS_MAKE_SOCK
+            | ACCESS_FS_MAKE_FIFO
+            | ACCESS_FS_MAKE_BLOCK
+            | ACCESS_FS_MAKE_SYM;
+        if abi >= 2 {
+            access |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            access |= ACCESS_FS_TRUNCATE;
+        }
+        access
+    }
+
+    /// Deny `access` everywhere except beneath `dir`, for this process and
+    /// whatever it executes
+    pub fn restrict_self(access: u64, dir: Option<RawFd>) -> io::Result<()> {
+        let attr = RulesetAttr { handled_access_fs: access };
+        // SAFETY: plain system calls on structs that outlive them
+        unsafe {
+            let ruleset = libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            );
+            if ruleset < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ruleset = ruleset as libc::c_int;
+            if let Some(dir) = dir {
+                let rule = PathBeneathAttr { allowed_access: access, parent_fd: dir };
+                if libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset,
+                    RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0,
+                ) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::close(ruleset);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::toolbox::execution::{spawn_tool_in, ExecutionLimits, ToolExitStatus};
+
+    fn limits() -> ExecutionLimits {
+        ExecutionLimits { timeout: Some(std::time::Duration::from_secs(10)), max_output_bytes: 64 * 1024 }
+    }
+
+    #[tokio::test]
+    async fn test_restricted_tool_writes_only_to_its_directory() {
+        let tool_dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let script = format!(
+            "echo ok > inside.txt && echo leaked > {}/outside.txt",
+            outside.path().display()
+        );
+        let args = ["-c".to_string(), script];
+        let spawned = spawn_tool_in(Path::new("sh"), &args, Some(tool_dir.path()), limits(), &ToolSandbox::Restricted);
+
+        if landlock::abi_version().is_err() {
+            // Without Landlock the tool is refused, never run unconfined
+            assert!(spawned.err().unwrap().to_string().contains("Landlock"));
+            return;
+        }
+        let output = spawned.unwrap().wait().await;
+        assert_ne!(output.status, ToolExitStatus::Exited(Some(0)));
+        assert!(tool_dir.path().join("inside.txt").exists());
+        assert!(!outside.path().join("outside.txt").exists());
+
+        // The agent itself is not confined
+        std::fs::write(outside.path().join("agent.txt"), "ok").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_sandbox_account_is_refused() {
+        let sandbox = ToolSandbox::User { name: "ghostlink-no-such-account".to_string() };
+        let error = spawn_tool_in(Path::new("true"), &[], None, limits(), &sandbox).err().unwrap();
+        assert!(error.to_string().contains("No account named ghostlink-no-such-account"));
+
+        let root = ToolSandbox::User { name: "root".to_string() };
+        assert!(spawn_tool_in(Path::new("true"), &[], None, limits(), &root).is_err());
+    }
+}
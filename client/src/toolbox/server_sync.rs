@@ -1,12 +1,21 @@
-use anyhow::Result;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::time::{Duration, interval};
-use tracing::{debug, info, error};
+use std::path::Path;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{Duration, interval, sleep};
+use tracing::{debug, info, error, warn};
 use uuid::Uuid;
 
-use super::{Tool, ToolboxConfig};
+use super::template::{shell_quote, ToolArg, ToolArgType};
+use super::{Tool, ToolCategory, ToolboxConfig};
+use crate::error::{GhostLinkError, Result};
+
+/// Attempts per download before giving up
+const DOWNLOAD_ATTEMPTS: u32 = 4;
+/// Delay before the first retry; doubled on every further attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ToolSyncRequest {
@@ -31,28 +40,246 @@ struct ServerTool {
     last_modified: chrono::DateTime<chrono::Utc>,
 }
 
+/// Tool entry in the server's `/api/toolbox/available` manifest
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestTool {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub category: String,
+    pub version: String,
+    #[serde(default)]
+    pub checksum: String,
+    pub file_path: String,
+    #[serde(default)]
+    pub file_size: u64,
+    #[serde(default)]
+    pub permissions: ManifestPermissions,
+    #[serde(default)]
+    pub parameters: Vec<ManifestParameter>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestPermissions {
+    #[serde(default)]
+    pub requires_admin: bool,
+}
+
+/// A tool parameter as the server describes it
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestParameter {
+    pub name: String,
+    pub parameter_type: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub default_value: Option<String>,
+    #[serde(default)]
+    pub options: Option<Vec<String>>,
+}
+
+impl ManifestParameter {
+    fn into_arg(self) -> ToolArg {
+        let arg_type = match self.parameter_type.as_str() {
+            "Integer" => ToolArgType::Int,
+            "Boolean" => ToolArgType::Bool,
+            "File" | "Directory" => ToolArgType::Path,
+            "Choice" => ToolArgType::Enum(self.options.unwrap_or_default()),
+            _ => ToolArgType::String,
+        };
+
+        ToolArg {
+            name: self.name,
+            description: self.description,
+            arg_type,
+            default: self.default_value,
+            required: self.required,
+        }
+    }
+}
+
+impl ManifestTool {
+    /// Convert to a server-managed tool whose command is the downloaded
+    /// file, followed by a placeholder for each server parameter
+    pub fn into_tool(self, auto_update: bool) -> Tool {
+        let file_name = Path::new(&self.file_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.id.to_string());
+        
+        let args_template: Vec<ToolArg> = self.parameters.into_iter().map(ManifestParameter::into_arg).collect();
+        let command = if args_template.is_empty() {
+            file_name
+        } else {
+            let placeholders: Vec<String> = args_template.iter().map(|arg| format!("{{{{{}}}}}", arg.name)).collect();
+            format!("{} {}", shell_quote(&file_name), placeholders.join(" "))
+        };
+
+        Tool {
+            id: self.id,
+            name: self.name,
+            description: self.description,
+            command,
+            icon_path: None,
+            category: ToolCategory::from_server_category(&self.category),
+            version: self.version,
+            checksum: self.checksum,
+            is_portable: true,
+            requires_admin: self.permissions.requires_admin,
+            auto_update,
+            server_managed: true,
+            args_template,
+        }
+    }
+}
+
+/// Outcome of a single download attempt
+enum AttemptError {
+    /// Worth another attempt: connection problems and server errors
+    Retry(GhostLinkError),
+    /// Retrying will not help, e.g. the tool does not exist
+    Fatal(GhostLinkError),
+}
+
+impl From<reqwest::Error> for AttemptError {
+    fn from(e: reqwest::Error) -> Self {
+        AttemptError::Retry(e.into())
+    }
+}
+
+impl From<std::io::Error> for AttemptError {
+    fn from(e: std::io::Error) -> Self {
+        AttemptError::Fatal(e.into())
+    }
+}
+
 pub struct ServerSync {
     client: Client,
     config: ToolboxConfig,
     server_url: String,
+    auth_token: Option<String>,
     last_sync: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl ServerSync {
     pub fn new(config: ToolboxConfig, server_url: String) -> Self {
-        let client = Client::builder()
+        let client = crate::connection::proxy::http_builder()
             .timeout(Duration::from_secs(30))
             .build()
             .expect("Failed to create HTTP client");
             
+        let auth_token = config.auth_token.clone();
         Self {
             client,
             config,
-            server_url,
+            server_url: server_url.trim_end_matches('/').to_string(),
+            auth_token,
             last_sync: None,
         }
     }
     
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.auth_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+    
+    /// Fetch the tools the server currently publishes
+    pub async fn fetch_manifest(&self) -> Result<Vec<ManifestTool>> {
+        let url = format!("{}/api/toolbox/available", self.server_url);
+        let response = self.authorize(self.client.get(&url)).send().await?;
+        
+        if !response.status().is_success() {
+            return Err(GhostLinkError::Other(format!("Toolbox manifest request failed: {}", response.status())));
+        }
+        
+        // The server groups its tools by category
+        let categories: HashMap<String, Vec<ManifestTool>> = response.json().await?;
+        let mut tools: Vec<ManifestTool> = categories.into_values().flatten().collect();
+        tools.sort_by_key(|tool| tool.id);
+        tools.dedup_by_key(|tool| tool.id);
+        
+        debug!("Server manifest lists {} tools", tools.len());
+        Ok(tools)
+    }
+    
+    /// Download URL of a server-managed tool
+    pub fn tool_download_url(&self, tool_id: &Uuid) -> String {
+        format!("{}/api/toolbox/download/{}", self.server_url, tool_id)
+    }
+    
+    /// Download `url` into `dest`, resuming from a partial file when the
+    /// server honours range requests and retrying with backoff otherwise.
+    /// Returns the size of the completed file.
+    pub async fn download_to_file(&self, url: &str, dest: &Path) -> Result<u64> {
+        let mut attempt = 0;
+        loop {
+            match self.download_attempt(url, dest).await {
+                Ok(size) => return Ok(size),
+                Err(AttemptError::Retry(e)) if attempt + 1 < DOWNLOAD_ATTEMPTS => {
+                    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+                    warn!("Download of {} failed (attempt {}): {}; retrying in {:?}", url, attempt + 1, e, delay);
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(AttemptError::Retry(e)) | Err(AttemptError::Fatal(e)) => return Err(e),
+            }
+        }
+    }
+    
+    async fn download_attempt(&self, url: &str, dest: &Path) -> std::result::Result<u64, AttemptError> {
+        let resume_from = match tokio::fs::metadata(dest).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        
+        let mut request = self.authorize(self.client.get(url));
+        if resume_from > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", resume_from));
+        }
+        
+        let mut response = request.send().await?;
+        let status = response.status();
+        
+        let append = match status {
+            StatusCode::PARTIAL_CONTENT => true,
+            StatusCode::OK => false,
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                // The partial file does not match the server's copy; start over
+                tokio::fs::remove_file(dest).await?;
+                return Err(AttemptError::Retry(GhostLinkError::Other("Stale partial download discarded".to_string())));
+            }
+            status if status.is_server_error() => {
+                return Err(AttemptError::Retry(GhostLinkError::Other(format!("Tool download failed: {}", status))));
+            }
+            status => {
+                return Err(AttemptError::Fatal(GhostLinkError::Other(format!("Tool download failed: {}", status))));
+            }
+        };
+        
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(dest)
+            .await?;
+        
+        let mut size = if append { resume_from } else { 0 };
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            size += chunk.len() as u64;
+        }
+        file.flush().await?;
+        
+        Ok(size)
+    }
+    
     pub async fn sync_tools(&mut self) -> Result<(Vec<Tool>, Vec<Uuid>)> {
         let sync_url = format!("{}/api/toolbox/sync", self.server_url);
         
@@ -62,14 +289,13 @@ impl ServerSync {
             last_sync: self.last_sync,
         };
         
-        let response = self.client
-            .post(&sync_url)
+        let response = self.authorize(self.client.post(&sync_url))
             .json(&request)
             .send()
             .await?;
             
         if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Server sync failed: {}", response.status()));
+            return Err(GhostLinkError::Other(format!("Server sync failed: {}", response.status())));
         }
         
         let sync_response: ToolSyncResponse = response.json().await?;
@@ -97,7 +323,7 @@ impl ServerSync {
             .await?;
             
         if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Tool download failed: {}", response.status()));
+            return Err(GhostLinkError::Other(format!("Tool download failed: {}", response.status())));
         }
         
         let data = response.bytes().await?;
@@ -121,7 +347,7 @@ impl ServerSync {
             .await?;
             
         if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Tool upload failed: {}", response.status()));
+            return Err(GhostLinkError::Other(format!("Tool upload failed: {}", response.status())));
         }
         
         info!("Uploaded tool: {}", tool.name);
@@ -137,7 +363,7 @@ impl ServerSync {
             .await?;
             
         if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Tool deletion failed: {}", response.status()));
+            return Err(GhostLinkError::Other(format!("Tool deletion failed: {}", response.status())));
         }
         
         info!("Deleted tool from server: {}", tool_id);
@@ -180,7 +406,7 @@ impl ServerSync {
             .await?;
             
         if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Update check failed: {}", response.status()));
+            return Err(GhostLinkError::Other(format!("Update check failed: {}", response.status())));
         }
         
         let updated_tools: Vec<Tool> = response.json().await?;
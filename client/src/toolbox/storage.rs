@@ -1,4 +1,3 @@
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use tokio::fs;
@@ -6,6 +5,7 @@ use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 use super::{Tool, ToolboxConfig};
+use crate::error::{GhostLinkError, Result};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ToolboxStorage {
@@ -70,14 +70,14 @@ impl LocalStorage {
         if source_path.is_file() {
             // Single file tool
             let file_name = source_path.file_name()
-                .ok_or_else(|| anyhow::anyhow!("Invalid source file path"))?;
+                .ok_or_else(|| GhostLinkError::Other("Invalid source file path".to_string()))?;
             let dest_path = tool_dir.join(file_name);
             fs::copy(source_path, dest_path).await?;
         } else if source_path.is_dir() {
             // Directory tool
             copy_dir_all_sync(source_path, &tool_dir)?;
         } else {
-            return Err(anyhow::anyhow!("Source path does not exist: {}", source_path.display()));
+            return Err(GhostLinkError::Other(format!("Source path does not exist: {}", source_path.display())));
         }
         
         // Set executable permissions on Unix
@@ -0,0 +1,299 @@
+//! Argument templates for toolbox commands.
+//!
+//! A tool with an argument template has a command line with `{{name}}`
+//! placeholders, e.g. `nmap -p {{ports}} {{host}}`. Values are validated
+//! against the template, quoted and substituted, and the result is split
+//! into arguments without ever passing through a shell. Placeholders must
+//! stand outside quotes in the template since the substituted value is
+//! quoted already.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::{GhostLinkError, Result};
+
+/// A variable a tool's command line accepts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolArg {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(rename = "type")]
+    pub arg_type: ToolArgType,
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolArgType {
+    String,
+    Int,
+    Bool,
+    Path,
+    /// One of the listed values
+    Enum(Vec<String>),
+}
+
+impl ToolArgType {
+    fn describe(&self) -> String {
+        match self {
+            ToolArgType::String => "string".to_string(),
+            ToolArgType::Int => "int".to_string(),
+            ToolArgType::Bool => "bool".to_string(),
+            ToolArgType::Path => "path".to_string(),
+            ToolArgType::Enum(options) => options.join("|"),
+        }
+    }
+}
+
+impl ToolArg {
+    /// Check a value against the argument's type
+    pub fn validate(&self, value: &str) -> Result<()> {
+        let valid = match &self.arg_type {
+            ToolArgType::String => true,
+            ToolArgType::Int => value.trim().parse::<i64>().is_ok(),
+            ToolArgType::Bool => matches!(
+                value.to_lowercase().as_str(),
+                "true" | "false" | "yes" | "no" | "1" | "0"
+            ),
+            ToolArgType::Path => !value.is_empty() && !value.contains('\0'),
+            ToolArgType::Enum(options) => options.iter().any(|option| option == value),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(GhostLinkError::Other(format!(
+                "Invalid value for '{}': {:?} is not a valid {}",
+                self.name, value, self.arg_type.describe()
+            )))
+        }
+    }
+}
+
+/// Required arguments that have neither a value nor a default
+pub fn missing_required<'a>(template: &'a [ToolArg], vars: &HashMap<String, String>) -> Vec<&'a ToolArg> {
+    template
+        .iter()
+        .filter(|arg| arg.required && arg.default.is_none() && !vars.contains_key(&arg.name))
+        .collect()
+}
+
+/// Validate `vars` against the template and fill in defaults
+pub fn resolve_vars(template: &[ToolArg], vars: &HashMap<String, String>) -> Result<HashMap<String, String>> {
+    if let Some(unknown) = vars.keys().find(|name| !template.iter().any(|arg| &arg.name == *name)) {
+        return Err(GhostLinkError::Other(format!("Unknown variable: {}", unknown)));
+    }
+
+    let missing = missing_required(template, vars);
+    if !missing.is_empty() {
+        let names: Vec<&str> = missing.iter().map(|arg| arg.name.as_str()).collect();
+        return Err(GhostLinkError::Other(format!("Missing required variables: {}", names.join(", "))));
+    }
+
+    let mut values = HashMap::new();
+    for arg in template {
+        if let Some(value) = vars.get(&arg.name).or(arg.default.as_ref()) {
+            arg.validate(value)?;
+            values.insert(arg.name.clone(), value.clone());
+        }
+    }
+
+    Ok(values)
+}
+
+/// Replace `{{name}}` placeholders with quoted values. Placeholders for
+/// optional variables without a value are removed.
+pub fn render_command(command: &str, values: &HashMap<String, String>, template: &[ToolArg]) -> Result<String> {
+    let mut rendered = String::with_capacity(command.len());
+    let mut rest = command;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| GhostLinkError::Other(format!("Unterminated placeholder in command: {}", command)))?;
+        let name = after[..end].trim();
+
+        match values.get(name) {
+            Some(value) => rendered.push_str(&shell_quote(value)),
+            None if template.iter().any(|arg| arg.name == name) => {}
+            None => return Err(GhostLinkError::Other(format!("Command uses undeclared variable: {}", name))),
+        }
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// Quote a value so it survives `split_command_line` as one argument
+pub fn shell_quote(value: &str) -> String {
+    let safe = !value.is_empty()
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c));
+    if safe {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Split a command line into arguments. Single quotes are literal, double
+/// quotes allow `\"` and `\\`; backslashes elsewhere are kept as-is so
+/// Windows paths need no escaping.
+pub fn split_command_line(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(GhostLinkError::Other(format!("Unterminated quote in command: {}", line))),
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"') | Some('\\')) => {
+                            current.push(chars.next().unwrap_or('\\'));
+                        }
+                        Some(c) => current.push(c),
+                        None => return Err(GhostLinkError::Other(format!("Unterminated quote in command: {}", line))),
+                    }
+                }
+            }
+            '\\' if chars.peek() == Some(&'\'') => {
+                // The `'\''` sequence emitted by `shell_quote`
+                in_arg = true;
+                current.push(chars.next().unwrap_or('\''));
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// Usage text listing a tool's variables
+pub fn usage(tool_name: &str, template: &[ToolArg]) -> String {
+    let mut text = format!("Usage: toolbox run \"{}\"", tool_name);
+    for arg in template {
+        let var = format!("--var {}=<{}>", arg.name, arg.arg_type.describe());
+        if arg.required && arg.default.is_none() {
+            text.push_str(&format!(" {}", var));
+        } else {
+            text.push_str(&format!(" [{}]", var));
+        }
+    }
+
+    for arg in template {
+        text.push_str(&format!("\n  {:<16} {}", arg.name, arg.description));
+        if let Some(default) = &arg.default {
+            text.push_str(&format!(" (default: {})", default));
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template() -> Vec<ToolArg> {
+        vec![
+            ToolArg {
+                name: "host".to_string(),
+                description: "Target host".to_string(),
+                arg_type: ToolArgType::String,
+                default: None,
+                required: true,
+            },
+            ToolArg {
+                name: "port".to_string(),
+                description: "Port".to_string(),
+                arg_type: ToolArgType::Int,
+                default: Some("443".to_string()),
+                required: false,
+            },
+            ToolArg {
+                name: "proto".to_string(),
+                description: "Protocol".to_string(),
+                arg_type: ToolArgType::Enum(vec!["tcp".to_string(), "udp".to_string()]),
+                default: None,
+                required: false,
+            },
+        ]
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn argv(command: &str, pairs: &[(&str, &str)]) -> Result<Vec<String>> {
+        let values = resolve_vars(&template(), &vars(pairs))?;
+        split_command_line(&render_command(command, &values, &template())?)
+    }
+
+    #[test]
+    fn test_substitution_with_defaults() {
+        let args = argv("portcheck {{host}} -p {{port}} {{proto}}", &[("host", "srv01")]).unwrap();
+        assert_eq!(args, vec!["portcheck", "srv01", "-p", "443"]);
+
+        let args = argv("portcheck {{host}} -p {{port}} {{proto}}", &[("host", "srv01"), ("port", "22"), ("proto", "udp")]).unwrap();
+        assert_eq!(args, vec!["portcheck", "srv01", "-p", "22", "udp"]);
+    }
+
+    #[test]
+    fn test_quoting_keeps_values_intact() {
+        for value in ["my host", "it's", "say \"hi\"", "a;rm -rf /", "$(whoami)", ""] {
+            let args = argv("\"C:\\Program Files\\tool.exe\" --host {{host}}", &[("host", value)]).unwrap();
+            assert_eq!(args, vec!["C:\\Program Files\\tool.exe", "--host", value], "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_validation_failures() {
+        let err = argv("t {{host}}", &[]).unwrap_err().to_string();
+        assert!(err.contains("Missing required variables: host"), "{}", err);
+
+        let err = argv("t {{host}}", &[("host", "a"), ("port", "http")]).unwrap_err().to_string();
+        assert!(err.contains("'port'"), "{}", err);
+
+        let err = argv("t {{host}}", &[("host", "a"), ("proto", "icmp")]).unwrap_err().to_string();
+        assert!(err.contains("tcp|udp"), "{}", err);
+
+        assert!(argv("t {{host}}", &[("host", "a"), ("user", "x")]).is_err());
+        assert!(argv("t {{target}}", &[("host", "a")]).is_err());
+
+        let template = template();
+        assert_eq!(missing_required(&template, &vars(&[("port", "1")])).len(), 1);
+        assert!(usage("PortCheck", &template).contains("--var host=<string> [--var port=<int>]"));
+    }
+}
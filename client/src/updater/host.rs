@@ -0,0 +1,93 @@
+//! What the updater needs from the machine it runs on
+//!
+//! Swapping binaries is plain renames inside the binary's directory. Windows
+//! keeps a running image open, so there it can be renamed away but not
+//! deleted until its process exits; [`UpdateHost::discard`] falls back to
+//! deleting it on the next reboot.
+
+use anyhow::{Context, Result};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+pub trait UpdateHost: Send + Sync {
+    /// Delete a binary that is no longer needed, possibly not right away
+    fn discard(&self, path: &Path) -> io::Result<()>;
+
+    /// Restart the agent on whichever binary is now installed
+    fn restart(&self) -> Result<()>;
+}
+
+/// Restarts by shutting the agent down cleanly and flagging the restart,
+/// which `main` carries out with [`restart_process`]
+pub struct SystemHost {
+    shutdown: mpsc::Sender<()>,
+    restart_pending: Arc<AtomicBool>,
+}
+
+impl SystemHost {
+    pub fn new(shutdown: mpsc::Sender<()>, restart_pending: Arc<AtomicBool>) -> Self {
+        Self { shutdown, restart_pending }
+    }
+}
+
+impl UpdateHost for SystemHost {
+    fn discard(&self, path: &Path) -> io::Result<()> {
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            #[cfg(windows)]
+            Err(_) => delete_on_reboot(path),
+            #[cfg(not(windows))]
+            Err(e) => Err(e),
+        }
+    }
+
+    fn restart(&self) -> Result<()> {
+        self.restart_pending.store(true, Ordering::SeqCst);
+        match self.shutdown.try_send(()) {
+            // A full channel means a shutdown is already on its way
+            Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => anyhow::bail!("Agent is no longer running"),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn delete_on_reboot(path: &Path) -> io::Result<()> {
+    use windows::core::{HSTRING, PCWSTR};
+    use windows::Win32::Storage::FileSystem::{MoveFileExW, MOVEFILE_DELAY_UNTIL_REBOOT};
+
+    unsafe { MoveFileExW(&HSTRING::from(path.as_os_str()), PCWSTR::null(), MOVEFILE_DELAY_UNTIL_REBOOT) }
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Start the agent again from `binary` with the same arguments.
+///
+/// `binary` is where the agent is installed rather than `current_exe`,
+/// which follows the running image to its backup name after a swap. Unix
+/// replaces this process in place. On Windows the service exits with an
+/// error and the service manager's recovery actions start the new binary;
+/// a foreground agent starts it and exits.
+pub fn restart_process(binary: &Path) -> Result<()> {
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let error = Command::new(binary).args(&args).exec();
+        Err(error).with_context(|| format!("Failed to restart into {}", binary.display()))
+    }
+
+    #[cfg(not(unix))]
+    {
+        Command::new(binary)
+            .args(&args)
+            .spawn()
+            .with_context(|| format!("Failed to restart into {}", binary.display()))?;
+        std::process::exit(0);
+    }
+}
@@ -0,0 +1,701 @@
+//! Agent self-update
+//!
+//! Every `check_interval_secs` the agent asks the server for the newest
+//! release on its channel. A newer release is only installed if its
+//! manifest is signed by the release key pinned in [`UpdatePolicy`] and the
+//! download matches the manifest's size and SHA-256.
+//!
+//! Installing renames the running binary aside as a backup, moves the
+//! download into its place and restarts. The new version then has
+//! [`MAX_UNCONFIRMED_STARTS`] starts to reach the relay before the backup
+//! is put back. The state between those steps lives in a small JSON file so
+//! it survives the restarts, and a rolled back update is reported with each
+//! heartbeat until a later one succeeds.
+
+pub mod host;
+pub mod version;
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use parking_lot::Mutex;
+use ring::digest::{Context as DigestContext, SHA256};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::config::ClientConfig;
+use host::UpdateHost;
+pub use version::Version;
+
+/// Prefix of the bytes a release signature covers; must match the server
+pub const RELEASE_CONTEXT: &str = "ghostlink-release-v1";
+
+/// Starts a new version gets to reach the relay before it is rolled back
+pub const MAX_UNCONFIRMED_STARTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseChannel {
+    #[default]
+    Stable,
+    Beta,
+}
+
+impl fmt::Display for ReleaseChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReleaseChannel::Stable => write!(f, "stable"),
+            ReleaseChannel::Beta => write!(f, "beta"),
+        }
+    }
+}
+
+/// Auto-update policy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpdatePolicy {
+    /// Check for and install new releases
+    pub enabled: bool,
+    pub channel: ReleaseChannel,
+    /// Seconds between release checks
+    pub check_interval_secs: u64,
+    /// Base64 Ed25519 key releases must be signed with; nothing is installed
+    /// until one is pinned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_public_key: Option<String>,
+    /// Server to fetch releases from, if not the relay's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_server: Option<String>,
+}
+
+impl Default for UpdatePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            channel: ReleaseChannel::Stable,
+            check_interval_secs: 6 * 60 * 60,
+            release_public_key: None,
+            release_server: None,
+        }
+    }
+}
+
+/// A published agent build, as served by `GET /api/releases/latest`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseManifest {
+    pub version: String,
+    pub channel: ReleaseChannel,
+    pub platform: String,
+    pub arch: String,
+    pub size: u64,
+    /// Hex SHA-256 of the binary
+    pub sha256: String,
+    /// Base64 Ed25519 signature over [`ReleaseManifest::signed_payload`]
+    pub signature: String,
+    #[serde(default)]
+    pub notes: Option<String>,
+    pub download_url: String,
+}
+
+impl ReleaseManifest {
+    /// Bytes the release key signs
+    pub fn signed_payload(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}",
+            RELEASE_CONTEXT, self.version, self.channel, self.platform, self.arch, self.size, self.sha256
+        )
+    }
+
+    /// Check the signature against the pinned release key
+    pub fn verify(&self, public_key: &[u8]) -> Result<()> {
+        let signature = BASE64.decode(&self.signature).context("Release signature is not valid base64")?;
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(self.signed_payload().as_bytes(), &signature)
+            .map_err(|_| anyhow::anyhow!("Release {} is not signed by the pinned release key", self.version))
+    }
+}
+
+/// Where an update stands, persisted across restarts
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum UpdateState {
+    #[default]
+    Idle,
+    /// A verified download waits to be installed
+    Staged { version: String, path: PathBuf },
+    /// The new binary is in place but has not reached the relay yet
+    Installed {
+        version: String,
+        previous_version: String,
+        backup: PathBuf,
+        starts: u32,
+    },
+    /// The update to `version` was abandoned or rolled back
+    Failed { version: String, error: String },
+}
+
+/// Update outcome reported with heartbeats
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpdateReport {
+    /// "pending" while a new version proves itself, "failed" once an update
+    /// was abandoned or rolled back
+    pub status: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct Updater {
+    channel: ReleaseChannel,
+    public_key: Vec<u8>,
+    manifest_url: Url,
+    /// Where the agent is installed; captured at startup since the running
+    /// image moves to the backup name during a swap
+    binary_path: PathBuf,
+    state_path: PathBuf,
+    current_version: Version,
+    host: Arc<dyn UpdateHost>,
+    state: Mutex<UpdateState>,
+    http: reqwest::Client,
+}
+
+impl Updater {
+    /// Updater for this agent, or `None` if auto-update is off or no release
+    /// key is pinned
+    pub fn new(config: &ClientConfig, host: Arc<dyn UpdateHost>) -> Result<Option<Self>> {
+        let policy = &config.update;
+        if !policy.enabled {
+            return Ok(None);
+        }
+        let Some(key) = &policy.release_public_key else {
+            warn!("Auto-update is enabled but no release key is pinned; not updating");
+            return Ok(None);
+        };
+        let public_key = BASE64.decode(key).context("Release public key is not valid base64")?;
+        if public_key.len() != 32 {
+            bail!("Release public key must be a 32-byte Ed25519 key");
+        }
+
+        let server = policy.release_server.as_deref().unwrap_or(&config.server_url);
+        let binary_path = std::env::current_exe().context("Cannot locate the agent binary")?;
+        let state_path = ClientConfig::default_path().with_file_name("update-state.json");
+        let updater = Self {
+            channel: policy.channel,
+            public_key,
+            manifest_url: release_url(server)?,
+            binary_path: PathBuf::new(),
+            state_path: PathBuf::new(),
+            current_version: Version::current(),
+            host,
+            state: Mutex::new(UpdateState::Idle),
+            http: crate::connection::proxy::http_client(),
+        };
+        Ok(Some(updater.with_paths(binary_path, state_path)))
+    }
+
+    /// Manage the binary at `binary_path`, keeping state in `state_path`
+    pub fn with_paths(mut self, binary_path: PathBuf, state_path: PathBuf) -> Self {
+        self.state = Mutex::new(load_state(&state_path));
+        self.binary_path = binary_path;
+        self.state_path = state_path;
+        self
+    }
+
+    /// Behave as if `version` were running
+    #[cfg(test)]
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.current_version = version;
+        self
+    }
+
+    pub fn binary_path(&self) -> &Path {
+        &self.binary_path
+    }
+
+    pub fn state(&self) -> UpdateState {
+        self.state.lock().clone()
+    }
+
+    /// What to report with heartbeats
+    pub fn report(&self) -> Option<UpdateReport> {
+        match &*self.state.lock() {
+            UpdateState::Installed { version, .. } => Some(UpdateReport {
+                status: "pending".to_string(),
+                version: version.clone(),
+                error: None,
+            }),
+            UpdateState::Failed { version, error } => Some(UpdateReport {
+                status: "failed".to_string(),
+                version: version.clone(),
+                error: Some(error.clone()),
+            }),
+            UpdateState::Idle | UpdateState::Staged { .. } => None,
+        }
+    }
+
+    /// Settle an update the last restart interrupted: count the new
+    /// version's starts and roll it back once it has used them up.
+    ///
+    /// Returns true if the agent is restarting onto the previous binary.
+    pub fn on_startup(&self) -> Result<bool> {
+        match self.state() {
+            UpdateState::Staged { path, .. } => {
+                // Stopped before the swap; the release is fetched again next check
+                let _ = std::fs::remove_file(&path);
+                self.set_state(UpdateState::Idle)?;
+                Ok(false)
+            }
+            UpdateState::Installed { version, previous_version, backup, starts } => {
+                if version.parse::<Version>().ok().as_ref() != Some(&self.current_version) {
+                    // Someone else replaced the binary; the backup is of no use
+                    let _ = self.host.discard(&backup);
+                    self.set_state(UpdateState::Failed {
+                        error: format!("Version {} is running instead", self.current_version),
+                        version,
+                    })?;
+                    return Ok(false);
+                }
+                if starts >= MAX_UNCONFIRMED_STARTS {
+                    self.rollback(format!("Did not reach the relay in {} starts", starts))?;
+                    return Ok(true);
+                }
+                self.set_state(UpdateState::Installed { version, previous_version, backup, starts: starts + 1 })?;
+                Ok(false)
+            }
+            UpdateState::Idle | UpdateState::Failed { .. } => Ok(false),
+        }
+    }
+
+    /// The new version reached the relay, so keep it
+    pub fn confirm(&self) -> Result<()> {
+        let UpdateState::Installed { version, backup, .. } = self.state() else {
+            return Ok(());
+        };
+        if let Err(e) = self.host.discard(&backup) {
+            warn!("Failed to remove previous agent binary {}: {}", backup.display(), e);
+        }
+        info!("Update to {} confirmed", version);
+        self.set_state(UpdateState::Idle)
+    }
+
+    /// Check for a newer release and install it.
+    ///
+    /// Returns true once an update is installed and the agent is restarting.
+    pub async fn run_once(&self) -> Result<bool> {
+        let Some(manifest) = self.check().await? else {
+            return Ok(false);
+        };
+        info!("Updating agent from {} to {}", self.current_version, manifest.version);
+
+        // A failed download is retried next check; a failed install is not
+        let staged = self.download(&manifest).await?;
+        if let Err(e) = self.install(&manifest, &staged) {
+            let _ = std::fs::remove_file(&staged);
+            self.set_state(UpdateState::Failed { version: manifest.version.clone(), error: format!("{:#}", e) })?;
+            return Err(e.context(format!("Update to {} failed", manifest.version)));
+        }
+        self.host.restart()?;
+        Ok(true)
+    }
+
+    /// Newest signed release for this platform, if it is newer than the
+    /// running version and has not failed here before
+    pub async fn check(&self) -> Result<Option<ReleaseManifest>> {
+        let response = self
+            .http
+            .get(self.manifest_url.clone())
+            .query(&[
+                ("platform", std::env::consts::OS),
+                ("arch", std::env::consts::ARCH),
+                ("channel", &self.channel.to_string()),
+            ])
+            .send()
+            .await
+            .context("Release check failed")?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let manifest: ReleaseManifest = response
+            .error_for_status()
+            .context("Release check failed")?
+            .json()
+            .await
+            .context("Malformed release manifest")?;
+        self.accept(manifest)
+    }
+
+    fn accept(&self, manifest: ReleaseManifest) -> Result<Option<ReleaseManifest>> {
+        manifest.verify(&self.public_key)?;
+        // The signature covers these, so a server can't hand out another
+        // platform's build or a beta to a stable agent
+        if manifest.platform != std::env::consts::OS || manifest.arch != std::env::consts::ARCH {
+            bail!("Release {} is for {}/{}", manifest.version, manifest.platform, manifest.arch);
+        }
+        if self.channel == ReleaseChannel::Stable && manifest.channel != ReleaseChannel::Stable {
+            bail!("Release {} is not on the stable channel", manifest.version);
+        }
+
+        if manifest.version.parse::<Version>()? <= self.current_version {
+            return Ok(None);
+        }
+        if let UpdateState::Failed { version, .. } = &*self.state.lock() {
+            if *version == manifest.version {
+                debug!("Skipping release {}, which failed here before", version);
+                return Ok(None);
+            }
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Download a release next to the installed binary, checking it against
+    /// the manifest
+    pub async fn download(&self, manifest: &ReleaseManifest) -> Result<PathBuf> {
+        let url = self.manifest_url.join(&manifest.download_url).context("Invalid download URL")?;
+        let staged = self.sibling("new");
+        let downloaded = async {
+            let mut response = self.http.get(url).send().await?.error_for_status()?;
+            let mut file = tokio::fs::File::create(&staged).await?;
+            let mut hasher = DigestContext::new(&SHA256);
+            let mut size = 0u64;
+            while let Some(chunk) = response.chunk().await? {
+                size += chunk.len() as u64;
+                if size > manifest.size {
+                    bail!("Download is larger than the {} bytes in the manifest", manifest.size);
+                }
+                hasher.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.sync_all().await?;
+            Ok((size, hex::encode(hasher.finish())))
+        }
+        .await;
+
+        let verified = downloaded.and_then(|(size, sha256)| {
+            if size != manifest.size || !sha256.eq_ignore_ascii_case(&manifest.sha256) {
+                bail!("Downloaded binary does not match the manifest");
+            }
+            Ok(())
+        });
+        if let Err(e) = verified {
+            let _ = tokio::fs::remove_file(&staged).await;
+            return Err(e.context(format!("Download of {} failed", manifest.version)));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755)).await?;
+        }
+        self.set_state(UpdateState::Staged { version: manifest.version.clone(), path: staged.clone() })?;
+        Ok(staged)
+    }
+
+    /// Swap the staged binary in, keeping the running one as a backup
+    pub fn install(&self, manifest: &ReleaseManifest, staged: &Path) -> Result<()> {
+        let backup = self.sibling("old");
+        std::fs::rename(&self.binary_path, &backup).context("Failed to move the running binary aside")?;
+        if let Err(e) = std::fs::rename(staged, &self.binary_path) {
+            std::fs::rename(&backup, &self.binary_path).context("Failed to restore the running binary")?;
+            return Err(e).context("Failed to move the new binary into place");
+        }
+
+        info!("Installed agent {}", manifest.version);
+        self.set_state(UpdateState::Installed {
+            version: manifest.version.clone(),
+            previous_version: self.current_version.to_string(),
+            backup,
+            starts: 0,
+        })
+    }
+
+    /// Put the backup back and restart onto it
+    fn rollback(&self, reason: String) -> Result<()> {
+        let UpdateState::Installed { version, previous_version, backup, .. } = self.state() else {
+            return Ok(());
+        };
+        warn!("Rolling back update to {} ({}), restoring {}", version, reason, previous_version);
+
+        let failed = self.sibling("failed");
+        std::fs::rename(&self.binary_path, &failed).context("Failed to move the new binary aside")?;
+        if let Err(e) = std::fs::rename(&backup, &self.binary_path) {
+            std::fs::rename(&failed, &self.binary_path).context("Failed to put the new binary back")?;
+            return Err(e).context("Failed to restore the previous binary");
+        }
+        if let Err(e) = self.host.discard(&failed) {
+            warn!("Failed to remove rolled back binary {}: {}", failed.display(), e);
+        }
+
+        self.set_state(UpdateState::Failed { version, error: reason })?;
+        self.host.restart()
+    }
+
+    /// `binary_path` with `.suffix` appended, in the same directory so the
+    /// renames stay on one filesystem
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self.binary_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(suffix);
+        self.binary_path.with_file_name(name)
+    }
+
+    fn set_state(&self, state: UpdateState) -> Result<()> {
+        if let Some(parent) = self.state_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = self.state_path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(&state)?)?;
+        std::fs::rename(&temp, &self.state_path).context("Failed to save update state")?;
+        *self.state.lock() = state;
+        Ok(())
+    }
+}
+
+fn load_state(path: &Path) -> UpdateState {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable update state {}: {}", path.display(), e);
+            UpdateState::Idle
+        }),
+        Err(_) => UpdateState::Idle,
+    }
+}
+
+/// Release manifest endpoint on the same host as the relay
+pub fn release_url(server_url: &str) -> Result<Url> {
+    let mut url = Url::parse(server_url).context("Invalid server URL")?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        other => bail!("Unsupported server URL scheme: {}", other),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow::anyhow!("Cannot convert {} to an HTTP URL", server_url))?;
+    url.set_path("/api/releases/latest");
+    url.set_query(None);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Counts restarts instead of re-executing the test binary
+    #[derive(Default)]
+    struct MockHost {
+        restarts: AtomicUsize,
+    }
+
+    impl UpdateHost for MockHost {
+        fn discard(&self, path: &Path) -> std::io::Result<()> {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+
+        fn restart(&self) -> Result<()> {
+            self.restarts.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn signed_manifest(key: &Ed25519KeyPair, version: &str, binary: &[u8]) -> ReleaseManifest {
+        let mut manifest = ReleaseManifest {
+            version: version.to_string(),
+            channel: ReleaseChannel::Stable,
+            platform: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            size: binary.len() as u64,
+            sha256: hex::encode(ring::digest::digest(&SHA256, binary)),
+            signature: String::new(),
+            notes: None,
+            download_url: format!("/api/releases/{}/{}/{}/binary", std::env::consts::OS, std::env::consts::ARCH, version),
+        };
+        manifest.signature = BASE64.encode(key.sign(manifest.signed_payload().as_bytes()));
+        manifest
+    }
+
+    struct Fixture {
+        dir: TempDir,
+        host: Arc<MockHost>,
+        key: Ed25519KeyPair,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            std::fs::write(dir.path().join("ghostlink-client"), b"v1.0.0").unwrap();
+            Self { dir, host: Arc::new(MockHost::default()), key: key_pair() }
+        }
+
+        fn binary(&self) -> PathBuf {
+            self.dir.path().join("ghostlink-client")
+        }
+
+        /// A fresh updater, as the agent creates on each start
+        fn updater(&self, server: &str, version: &str) -> Updater {
+            let mut config = ClientConfig::new(format!("{}/relay/ws", server.replace("http", "ws")), None).unwrap();
+            config.update.release_public_key = Some(BASE64.encode(self.key.public_key().as_ref()));
+            Updater::new(&config, self.host.clone())
+                .unwrap()
+                .unwrap()
+                .with_paths(self.binary(), self.dir.path().join("update-state.json"))
+                .with_version(version.parse().unwrap())
+        }
+
+        fn restarts(&self) -> usize {
+            self.host.restarts.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn release_server(manifest: &ReleaseManifest, binary: &[u8]) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/releases/latest"))
+            .and(query_param("channel", "stable"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(manifest))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(manifest.download_url.as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(binary.to_vec()))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[test]
+    fn test_manifest_signature() {
+        let key = key_pair();
+        let public_key = key.public_key().as_ref().to_vec();
+        let manifest = signed_manifest(&key, "1.1.0", b"binary");
+        assert!(manifest.verify(&public_key).is_ok());
+
+        let mut tampered = manifest.clone();
+        tampered.sha256 = hex::encode(ring::digest::digest(&SHA256, b"other binary"));
+        assert!(tampered.verify(&public_key).is_err());
+
+        let mut promoted = manifest.clone();
+        promoted.channel = ReleaseChannel::Beta;
+        assert!(promoted.verify(&public_key).is_err());
+
+        assert!(manifest.verify(key_pair().public_key().as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_accepts_only_newer_releases() {
+        let fixture = Fixture::new();
+        let updater = fixture.updater("http://127.0.0.1:1", "1.0.0");
+
+        for (version, newer) in [("0.9.0", false), ("1.0.0", false), ("1.0.1-beta.1", true), ("1.0.1", true)] {
+            let mut manifest = signed_manifest(&fixture.key, version, b"binary");
+            if version.contains('-') {
+                manifest.channel = ReleaseChannel::Beta;
+                manifest.signature = BASE64.encode(fixture.key.sign(manifest.signed_payload().as_bytes()));
+                // Stable agents refuse beta builds outright
+                assert!(updater.accept(manifest).is_err());
+                continue;
+            }
+            assert_eq!(updater.accept(manifest).unwrap().is_some(), newer, "{}", version);
+        }
+
+        let mut foreign = signed_manifest(&fixture.key, "2.0.0", b"binary");
+        foreign.platform = "plan9".to_string();
+        foreign.signature = BASE64.encode(fixture.key.sign(foreign.signed_payload().as_bytes()));
+        assert!(updater.accept(foreign).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_is_confirmed_after_reconnect() {
+        let fixture = Fixture::new();
+        let manifest = signed_manifest(&fixture.key, "1.1.0", b"v1.1.0");
+        let server = release_server(&manifest, b"v1.1.0").await;
+
+        let updater = fixture.updater(&server.uri(), "1.0.0");
+        assert!(updater.run_once().await.unwrap());
+        assert_eq!(fixture.restarts(), 1);
+        assert_eq!(std::fs::read(fixture.binary()).unwrap(), b"v1.1.0");
+        assert_eq!(updater.report().unwrap().status, "pending");
+
+        // The restarted agent counts its start, then reaches the relay
+        let updater = fixture.updater(&server.uri(), "1.1.0");
+        assert!(!updater.on_startup().unwrap());
+        updater.confirm().unwrap();
+        assert_eq!(updater.state(), UpdateState::Idle);
+        assert!(updater.report().is_none());
+        assert!(!updater.sibling("old").exists());
+
+        // Nothing newer is left to install
+        assert!(!updater.run_once().await.unwrap());
+        assert_eq!(fixture.restarts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_update_rolls_back() {
+        let fixture = Fixture::new();
+        let manifest = signed_manifest(&fixture.key, "1.1.0", b"v1.1.0");
+        let server = release_server(&manifest, b"v1.1.0").await;
+        assert!(fixture.updater(&server.uri(), "1.0.0").run_once().await.unwrap());
+
+        // The new version keeps failing to reach the relay
+        for _ in 0..MAX_UNCONFIRMED_STARTS {
+            assert!(!fixture.updater(&server.uri(), "1.1.0").on_startup().unwrap());
+        }
+        let updater = fixture.updater(&server.uri(), "1.1.0");
+        assert!(updater.on_startup().unwrap());
+        assert_eq!(fixture.restarts(), 2);
+        assert_eq!(std::fs::read(fixture.binary()).unwrap(), b"v1.0.0");
+        assert!(!updater.sibling("failed").exists());
+
+        // The old version reports the failure and doesn't retry the release
+        let updater = fixture.updater(&server.uri(), "1.0.0");
+        assert!(!updater.on_startup().unwrap());
+        let report = updater.report().unwrap();
+        assert_eq!((report.status.as_str(), report.version.as_str()), ("failed", "1.1.0"));
+        assert!(!updater.run_once().await.unwrap());
+        assert_eq!(fixture.restarts(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_download_is_not_installed() {
+        let fixture = Fixture::new();
+        let manifest = signed_manifest(&fixture.key, "1.1.0", b"v1.1.0");
+        let server = release_server(&manifest, b"v1.1.X").await;
+
+        let updater = fixture.updater(&server.uri(), "1.0.0");
+        assert!(updater.run_once().await.is_err());
+        assert_eq!(fixture.restarts(), 0);
+        assert_eq!(std::fs::read(fixture.binary()).unwrap(), b"v1.0.0");
+        assert!(!updater.sibling("new").exists());
+        assert_eq!(updater.state(), UpdateState::Idle);
+    }
+
+    #[test]
+    fn test_release_url() {
+        assert_eq!(
+            release_url("wss://relay.example.com/relay/ws").unwrap().as_str(),
+            "https://relay.example.com/api/releases/latest"
+        );
+        assert_eq!(
+            release_url("ws://localhost:3000").unwrap().as_str(),
+            "http://localhost:3000/api/releases/latest"
+        );
+        assert!(release_url("ftp://example.com").is_err());
+    }
+}
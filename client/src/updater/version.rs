@@ -0,0 +1,128 @@
+//! Semantic version ordering for release checks
+
+use std::cmp::Ordering;
+
+/// Semantic version, ordered by semver precedence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// Pre-release identifiers, e.g. `beta.2`; build metadata is dropped
+    pub pre: Vec<String>,
+}
+
+impl Version {
+    /// Version of this binary
+    pub fn current() -> Self {
+        // Cargo only accepts semver package versions
+        env!("CARGO_PKG_VERSION").parse().expect("package version is semver")
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_start_matches('v');
+        let s = s.split('+').next().unwrap_or(s);
+        let (core, pre) = match s.split_once('-') {
+            Some((core, pre)) => (core, pre.split('.').map(str::to_string).collect()),
+            None => (s, Vec::new()),
+        };
+
+        let numbers: Vec<u64> = core
+            .split('.')
+            .map(|part| part.parse().map_err(|_| anyhow::anyhow!("Invalid version: {}", s)))
+            .collect::<Result<_, _>>()?;
+        let [major, minor, patch] = numbers[..] else {
+            anyhow::bail!("Invalid version: {}", s);
+        };
+        if pre.iter().any(String::is_empty) {
+            anyhow::bail!("Invalid pre-release in version: {}", s);
+        }
+        Ok(Self { major, minor, patch, pre })
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if self.is_prerelease() {
+            write!(f, "-{}", self.pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.is_prerelease(), other.is_prerelease()) {
+                (false, false) => Ordering::Equal,
+                // A release outranks its pre-releases
+                (false, true) => Ordering::Greater,
+                (true, false) => Ordering::Less,
+                (true, true) => compare_pre(&self.pre, &other.pre),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Numeric identifiers compare numerically and rank below alphanumeric ones
+fn compare_pre(a: &[String], b: &[String]) -> Ordering {
+    for (a, b) in a.iter().zip(b) {
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let ordered = [
+            "0.9.9", "1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta",
+            "1.0.0-beta.2", "1.0.0-beta.11", "1.0.0-rc.1", "1.0.0", "1.0.1", "1.2.0", "1.10.0", "2.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(version("v1.2.3"), version("1.2.3"));
+        assert_eq!(version("1.2.3+build.7"), version("1.2.3"));
+        assert_eq!(version("1.2.3-beta.1").to_string(), "1.2.3-beta.1");
+        assert!(version("1.2.3-rc.1").is_prerelease());
+        for invalid in ["", "1.2", "1.2.3.4", "1.x.3", "1.2.3-", "1.2.3-beta..1"] {
+            assert!(invalid.parse::<Version>().is_err(), "{:?}", invalid);
+        }
+        assert!(Version::current() >= version("0.1.0"));
+    }
+}
@@ -0,0 +1,618 @@
+//! The session window's egui app: drawing only, state lives in `WindowView`
+
+use eframe::egui::{self, Color32, RichText};
+use std::time::Duration;
+
+use crate::file_transfer::transfer::TransferState;
+use crate::input::{InputEvent, KeyCode};
+use crate::session::notes::NoteTemplate;
+use crate::session::shortcuts::hook::{self, KeyboardHook};
+use crate::session::shortcuts::{ComboDetector, HotkeyGuard, Platform, ShortcutAction, ShortcutLayer};
+use crate::session::window::{CommandExecution, SessionTab, WindowSnapshot};
+use crate::session::window_view::{SessionControl, WindowBridge, WindowRequest, WindowState, WindowView};
+
+use super::screen::RemoteScreen;
+
+/// Storage key of the persisted `WindowState`
+const STATE_KEY: &str = "session_window";
+
+/// Redraw at least this often so streamed output and new frames show up
+const REPAINT_INTERVAL: Duration = Duration::from_millis(100);
+
+const CONTROLS: [SessionControl; 3] = [
+    SessionControl::Backstage,
+    SessionControl::SuspendInput,
+    SessionControl::BlankScreen,
+];
+
+pub struct SessionApp {
+    bridge: WindowBridge,
+    view: WindowView,
+    screen: Option<RemoteScreen>,
+    texture: Option<egui::TextureHandle>,
+    shortcuts: ShortcutLayer<Box<dyn KeyboardHook>>,
+}
+
+impl SessionApp {
+    pub fn new(
+        cc: &eframe::CreationContext<'_>,
+        bridge: WindowBridge,
+        screen: Option<RemoteScreen>,
+        remote: Platform,
+    ) -> Self {
+        let state: WindowState = cc.storage
+            .and_then(|storage| eframe::get_value(storage, STATE_KEY))
+            .unwrap_or_default();
+        let detector = ComboDetector::new(Platform::local(), remote);
+        Self {
+            bridge,
+            view: WindowView::new(state),
+            screen,
+            texture: None,
+            shortcuts: ShortcutLayer::new(hook::platform_hook(), detector, HotkeyGuard::register()),
+        }
+    }
+
+    fn send(&self, requests: impl IntoIterator<Item = WindowRequest>) {
+        for request in requests {
+            self.bridge.send(request);
+        }
+    }
+
+    fn send_input(&self, events: &[InputEvent]) {
+        if let Some(screen) = &self.screen {
+            screen.send_input(events);
+        }
+    }
+
+    /// Keys for the remote machine while the Start tab shows: what the
+    /// window sees, and what the hook takes while system shortcuts are sent
+    fn keyboard(&mut self, ctx: &egui::Context) {
+        let on_screen = self.view.tab == SessionTab::Start && self.shortcuts.confirming().is_none();
+        let (focused, events, modifiers) = ctx.input(|i| (i.focused, i.events.clone(), i.modifiers));
+        self.shortcuts.set_passthrough(self.view.send_shortcuts);
+
+        let mut input = Vec::new();
+        if on_screen && self.shortcuts.window_keys() {
+            for event in &events {
+                match event {
+                    egui::Event::Key { key, pressed, modifiers, .. } => {
+                        let Some(code) = key_code(*key) else { continue };
+                        input.extend(self.shortcuts.modifiers(&held(modifiers)));
+                        // Printable keys arrive as text too, unless a command
+                        // modifier is down
+                        if !pressed || !is_text_key(code) || is_command(modifiers) {
+                            input.extend(self.shortcuts.key(code, *pressed));
+                        }
+                    }
+                    egui::Event::Text(text) => input.extend(self.shortcuts.text(text)),
+                    // The clipboard shortcuts come without their key events
+                    egui::Event::Copy | egui::Event::Cut | egui::Event::Paste(_) => {
+                        let code = match event {
+                            egui::Event::Copy => KeyCode::C,
+                            egui::Event::Cut => KeyCode::X,
+                            _ => KeyCode::V,
+                        };
+                        input.extend(self.shortcuts.modifiers(&held(&modifiers)));
+                        input.extend(self.shortcuts.key(code, true));
+                        input.extend(self.shortcuts.key(code, false));
+                    }
+                    _ => {}
+                }
+            }
+            input.extend(self.shortcuts.modifiers(&held(&modifiers)));
+        }
+        match self.shortcuts.update(focused && on_screen) {
+            Ok(taken) => input.extend(taken),
+            Err(e) => {
+                self.view.send_shortcuts = false;
+                self.view.status = Some(format!("System shortcuts stay on this computer: {}", e));
+            }
+        }
+        self.send_input(&input);
+    }
+
+    /// Upload the newest remote picture, if one arrived since the last frame
+    fn update_texture(&mut self, ctx: &egui::Context) {
+        let Some(frame) = self.screen.as_ref().and_then(RemoteScreen::take_frame) else {
+            return;
+        };
+        let image = egui::ColorImage::from_rgba_unmultiplied([frame.width as usize, frame.height as usize], &frame.rgba);
+        match &mut self.texture {
+            Some(texture) => texture.set(image, egui::TextureOptions::LINEAR),
+            None => self.texture = Some(ctx.load_texture("remote-screen", image, egui::TextureOptions::LINEAR)),
+        }
+    }
+
+    fn top_bar(&mut self, ctx: &egui::Context, snapshot: &WindowSnapshot) {
+        egui::TopBottomPanel::top("session_bar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                for tab in SessionTab::ALL {
+                    if ui.selectable_label(self.view.tab == tab, format!("{:?}", tab)).clicked() {
+                        let request = self.view.select_tab(tab);
+                        self.send(request);
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                for control in CONTROLS {
+                    if ui.selectable_label(control.is_on(snapshot), control.label()).clicked() {
+                        let request = self.view.toggle(control, snapshot);
+                        self.send([request]);
+                    }
+                }
+                ui.separator();
+                if ui.selectable_label(self.view.show_transfers, "File transfers").clicked() {
+                    self.view.show_transfers = !self.view.show_transfers;
+                }
+                if ui.selectable_label(self.view.show_toolbox, "Toolbox").clicked() {
+                    self.view.show_toolbox = !self.view.show_toolbox;
+                }
+                ui.separator();
+                let shortcuts = ui.selectable_label(self.view.send_shortcuts, "Send system shortcuts")
+                    .on_hover_text("Alt+Tab, the Windows key and media keys go to the remote screen while it has focus");
+                if shortcuts.clicked() {
+                    self.view.send_shortcuts = !self.view.send_shortcuts;
+                }
+                for action in ShortcutAction::TOOLBAR {
+                    let Some(label) = self.shortcuts.remote_label(action) else { continue };
+                    let button = ui.add_enabled(self.screen.is_some(), egui::Button::new(label))
+                        .on_hover_text(action.label());
+                    if button.clicked() {
+                        let events = self.shortcuts.press(action);
+                        self.send_input(&events);
+                    }
+                }
+                if snapshot.recording {
+                    ui.colored_label(Color32::RED, "● Recording");
+                }
+                if self.view.is_busy() {
+                    ui.spinner();
+                }
+            });
+            if let Some(status) = self.view.status.clone() {
+                ui.horizontal(|ui| {
+                    ui.colored_label(Color32::LIGHT_RED, status);
+                    if ui.small_button("Dismiss").clicked() {
+                        self.view.dismiss_status();
+                    }
+                });
+            }
+        });
+    }
+
+    fn toolbox(&mut self, ctx: &egui::Context, snapshot: &WindowSnapshot) {
+        egui::SidePanel::right("toolbox").resizable(true).default_width(220.0).show(ctx, |ui| {
+            ui.heading("Toolbox");
+            ui.label(RichText::new("Double-click a tool to run it").small().weak());
+            ui.separator();
+            if snapshot.tools.is_empty() {
+                ui.label("No tools available");
+            }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for tool in &snapshot.tools {
+                    let selected = self.view.selected_tool.as_deref() == Some(tool.name.as_str());
+                    let response = ui.selectable_label(selected, tool.name.as_str())
+                        .on_hover_text(tool.description.as_str());
+                    if response.double_clicked() {
+                        let requests = self.view.launch_tool(&tool.name);
+                        self.send(requests);
+                    } else if response.clicked() {
+                        self.view.selected_tool = Some(tool.name.clone());
+                    }
+                }
+            });
+        });
+    }
+
+    fn transfers(&mut self, ctx: &egui::Context, snapshot: &WindowSnapshot) {
+        egui::Window::new("File transfers").open(&mut self.view.show_transfers).show(ctx, |ui| {
+            if snapshot.transfers.is_empty() {
+                ui.label("No file transfers in this session");
+            }
+            for transfer in &snapshot.transfers {
+                let state = match &transfer.state {
+                    TransferState::Active => "transferring".to_string(),
+                    TransferState::Interrupted => "waiting to resume".to_string(),
+                    TransferState::Completed => "done".to_string(),
+                    TransferState::Failed(error) => format!("failed: {}", error),
+                    TransferState::Cancelled => "cancelled".to_string(),
+                };
+                ui.label(format!("{} ({})", transfer.filename, state));
+                ui.add(egui::ProgressBar::new(transfer.fraction() as f32).show_percentage());
+            }
+        });
+    }
+
+    fn start_tab(&self, ui: &mut egui::Ui) {
+        match &self.texture {
+            Some(texture) => {
+                ui.centered_and_justified(|ui| {
+                    ui.add(egui::Image::from_texture(egui::load::SizedTexture::from_handle(texture)).shrink_to_fit());
+                });
+            }
+            None => {
+                let status = self.screen.as_ref()
+                    .map(RemoteScreen::status)
+                    .unwrap_or_else(|| "The remote screen is unavailable".to_string());
+                ui.centered_and_justified(|ui| ui.label(status));
+            }
+        }
+    }
+
+    fn general_tab(ui: &mut egui::Ui, snapshot: &WindowSnapshot) {
+        let info = &snapshot.session_info;
+        egui::Grid::new("session_info").num_columns(2).striped(true).show(ui, |ui| {
+            let rows = [
+                ("Session", info.session_id.clone()),
+                ("Device", info.device_name.clone()),
+                ("Operating system", info.operating_system.clone()),
+                ("IP address", info.ip_address.clone()),
+                ("User", info.user_name.clone().unwrap_or_else(|| "-".to_string())),
+                ("Connected", info.connected_time.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+                ("Last activity", info.last_activity.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+            ];
+            for (name, value) in rows {
+                ui.label(name);
+                ui.label(value);
+                ui.end_row();
+            }
+        });
+    }
+
+    fn timeline_tab(ui: &mut egui::Ui, snapshot: &WindowSnapshot) {
+        egui::ScrollArea::vertical().stick_to_bottom(true).show(ui, |ui| {
+            for event in &snapshot.timeline {
+                ui.horizontal(|ui| {
+                    ui.monospace(event.timestamp.format("%H:%M:%S").to_string());
+                    ui.label(event.description.as_str());
+                });
+            }
+        });
+    }
+
+    fn messages_tab(&mut self, ui: &mut egui::Ui, snapshot: &WindowSnapshot) {
+        egui::TopBottomPanel::bottom("message_input").show_inside(ui, |ui| {
+            ui.horizontal(|ui| {
+                let input = ui.text_edit_singleline(&mut self.view.message_draft);
+                let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui.button("Send").clicked() || entered {
+                    let request = self.view.submit_message();
+                    self.send(request);
+                    input.request_focus();
+                }
+            });
+        });
+        egui::ScrollArea::vertical().stick_to_bottom(true).show(ui, |ui| {
+            for message in &snapshot.messages {
+                ui.horizontal_wrapped(|ui| {
+                    ui.monospace(message.timestamp.format("%H:%M").to_string());
+                    ui.strong(message.sender.as_str());
+                    ui.label(message.message.as_str());
+                });
+            }
+        });
+    }
+
+    fn commands_tab(&mut self, ui: &mut egui::Ui, snapshot: &WindowSnapshot) {
+        egui::TopBottomPanel::bottom("command_input").show_inside(ui, |ui| {
+            ui.add(
+                egui::TextEdit::multiline(&mut self.view.command_input)
+                    .code_editor()
+                    .desired_rows(3)
+                    .desired_width(f32::INFINITY)
+                    .hint_text("#timeout 30\n#!powershell\nGet-Service"),
+            );
+            ui.horizontal(|ui| {
+                let shortcut = ui.input(|i| i.key_pressed(egui::Key::Enter) && i.modifiers.ctrl);
+                if ui.button("Run").clicked() || shortcut {
+                    let request = self.view.submit_command();
+                    self.send(request);
+                }
+                ui.label(RichText::new("Ctrl+Enter runs the command").small().weak());
+            });
+        });
+        egui::ScrollArea::vertical().stick_to_bottom(true).show(ui, |ui| {
+            for execution in &snapshot.commands {
+                command_entry(ui, execution);
+            }
+        });
+    }
+
+    fn notes_tab(&mut self, ui: &mut egui::Ui, snapshot: &WindowSnapshot) {
+        egui::TopBottomPanel::top("ticket").show_inside(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Ticket");
+                let current = snapshot.ticket_ref.as_deref().unwrap_or("none");
+                let input = ui.add(egui::TextEdit::singleline(&mut self.view.ticket_draft).hint_text(current));
+                let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui.button("Link").clicked() || entered {
+                    let request = self.view.submit_ticket();
+                    self.send([request]);
+                }
+            });
+        });
+        egui::TopBottomPanel::bottom("note_input").show_inside(ui, |ui| {
+            ui.add(egui::TextEdit::multiline(&mut self.view.note_draft).desired_rows(3).desired_width(f32::INFINITY));
+            ui.horizontal(|ui| {
+                if let Some(template) = template_picker(ui, "note_template", &snapshot.note_templates) {
+                    self.view.apply_template(template);
+                }
+                ui.checkbox(&mut self.view.note_private, "Private");
+                if ui.button("Add note").clicked() {
+                    let request = self.view.submit_note();
+                    self.send(request);
+                }
+            });
+        });
+        egui::ScrollArea::vertical().stick_to_bottom(true).show(ui, |ui| {
+            for note in &snapshot.notes {
+                ui.horizontal(|ui| {
+                    ui.strong(note.author.as_str());
+                    ui.monospace(note.timestamp.format("%Y-%m-%d %H:%M").to_string());
+                    if note.closeout {
+                        ui.label(RichText::new("close-out").weak());
+                    }
+                    if note.is_private {
+                        ui.label(RichText::new("private").weak());
+                    }
+                    if note.edited_at.is_some() {
+                        ui.label(RichText::new("edited").weak());
+                    }
+                    if self.view.editing.is_none() && ui.small_button("Edit").clicked() {
+                        self.view.start_edit(note);
+                    }
+                });
+                match &mut self.view.editing {
+                    Some((id, draft)) if *id == note.id => {
+                        ui.add(egui::TextEdit::multiline(draft).desired_rows(3).desired_width(f32::INFINITY));
+                        ui.horizontal(|ui| {
+                            if ui.button("Save").clicked() {
+                                let request = self.view.submit_edit();
+                                self.send(request);
+                            }
+                            if ui.button("Cancel").clicked() {
+                                self.view.editing = None;
+                            }
+                        });
+                    }
+                    _ => {
+                        ui.label(note.content.as_str());
+                    }
+                }
+                ui.separator();
+            }
+        });
+    }
+
+    /// Asks before a combo that closes, locks or interrupts something is
+    /// sent while other session windows are open
+    fn shortcut_prompt(&mut self, ctx: &egui::Context, snapshot: &WindowSnapshot, action: ShortcutAction) {
+        let label = self.shortcuts.remote_label(action).unwrap_or_else(|| action.label().to_string());
+        egui::Window::new("Send shortcut?").collapsible(false).resizable(false).show(ctx, |ui| {
+            ui.label(format!(
+                "More than one session window is open. Send {} to {}?",
+                label, snapshot.session_info.device_name
+            ));
+            ui.horizontal(|ui| {
+                if ui.button("Send").clicked() {
+                    let events = self.shortcuts.confirm();
+                    self.send_input(&events);
+                }
+                if ui.button("Cancel").clicked() {
+                    self.shortcuts.cancel();
+                }
+            });
+        });
+    }
+
+    /// Asks for the close-out note the policy wants before the session ends
+    fn closeout_prompt(&mut self, ctx: &egui::Context, snapshot: &WindowSnapshot) {
+        egui::Window::new("Close-out note").collapsible(false).resizable(false).show(ctx, |ui| {
+            ui.label("This session needs a close-out note before it ends.");
+            ui.add(egui::TextEdit::multiline(&mut self.view.closeout_draft).desired_rows(5).desired_width(400.0));
+            ui.horizontal(|ui| {
+                if let Some(template) = template_picker(ui, "closeout_template", &snapshot.note_templates) {
+                    self.view.apply_closeout_template(template);
+                }
+                if ui.button("End session").clicked() {
+                    let request = self.view.submit_closeout();
+                    self.send(request);
+                }
+                if ui.button("Keep session open").clicked() {
+                    self.view.cancel_close();
+                }
+            });
+        });
+    }
+}
+
+/// Modifiers egui reports held, as keys
+fn held(modifiers: &egui::Modifiers) -> Vec<KeyCode> {
+    [
+        (modifiers.shift, KeyCode::Shift),
+        (modifiers.ctrl, KeyCode::Ctrl),
+        (modifiers.alt, KeyCode::Alt),
+        (modifiers.mac_cmd, KeyCode::Super),
+    ]
+    .into_iter()
+    .filter_map(|(down, key)| down.then_some(key))
+    .collect()
+}
+
+/// Whether a key with these modifiers is a command rather than text. Ctrl
+/// and Alt together are AltGr, which types.
+fn is_command(modifiers: &egui::Modifiers) -> bool {
+    let alt_command = modifiers.alt && !cfg!(target_os = "macos");
+    (modifiers.ctrl || modifiers.mac_cmd || alt_command) && !(modifiers.ctrl && alt_command)
+}
+
+fn is_text_key(key: KeyCode) -> bool {
+    use KeyCode::*;
+    matches!(
+        key,
+        A | B | C | D | E | F | G | H | I | J | K | L | M | N | O | P | Q | R | S | T | U | V | W | X | Y | Z
+            | Key0 | Key1 | Key2 | Key3 | Key4 | Key5 | Key6 | Key7 | Key8 | Key9 | Space
+    )
+}
+
+fn key_code(key: egui::Key) -> Option<KeyCode> {
+    use egui::Key;
+    let code = match key {
+        Key::ArrowDown => KeyCode::Down,
+        Key::ArrowLeft => KeyCode::Left,
+        Key::ArrowRight => KeyCode::Right,
+        Key::ArrowUp => KeyCode::Up,
+        Key::Escape => KeyCode::Escape,
+        Key::Tab => KeyCode::Tab,
+        Key::Backspace => KeyCode::Backspace,
+        Key::Enter => KeyCode::Enter,
+        Key::Space => KeyCode::Space,
+        Key::Insert => KeyCode::Insert,
+        Key::Delete => KeyCode::Delete,
+        Key::Home => KeyCode::Home,
+        Key::End => KeyCode::End,
+        Key::PageUp => KeyCode::PageUp,
+        Key::PageDown => KeyCode::PageDown,
+        Key::Num0 => KeyCode::Key0,
+        Key::Num1 => KeyCode::Key1,
+        Key::Num2 => KeyCode::Key2,
+        Key::Num3 => KeyCode::Key3,
+        Key::Num4 => KeyCode::Key4,
+        Key::Num5 => KeyCode::Key5,
+        Key::Num6 => KeyCode::Key6,
+        Key::Num7 => KeyCode::Key7,
+        Key::Num8 => KeyCode::Key8,
+        Key::Num9 => KeyCode::Key9,
+        Key::A => KeyCode::A,
+        Key::B => KeyCode::B,
+        Key::C => KeyCode::C,
+        Key::D => KeyCode::D,
+        Key::E => KeyCode::E,
+        Key::F => KeyCode::F,
+        Key::G => KeyCode::G,
+        Key::H => KeyCode::H,
+        Key::I => KeyCode::I,
+        Key::J => KeyCode::J,
+        Key::K => KeyCode::K,
+        Key::L => KeyCode::L,
+        Key::M => KeyCode::M,
+        Key::N => KeyCode::N,
+        Key::O => KeyCode::O,
+        Key::P => KeyCode::P,
+        Key::Q => KeyCode::Q,
+        Key::R => KeyCode::R,
+        Key::S => KeyCode::S,
+        Key::T => KeyCode::T,
+        Key::U => KeyCode::U,
+        Key::V => KeyCode::V,
+        Key::W => KeyCode::W,
+        Key::X => KeyCode::X,
+        Key::Y => KeyCode::Y,
+        Key::Z => KeyCode::Z,
+        Key::F1 => KeyCode::F1,
+        Key::F2 => KeyCode::F2,
+        Key::F3 => KeyCode::F3,
+        Key::F4 => KeyCode::F4,
+        Key::F5 => KeyCode::F5,
+        Key::F6 => KeyCode::F6,
+        Key::F7 => KeyCode::F7,
+        Key::F8 => KeyCode::F8,
+        Key::F9 => KeyCode::F9,
+        Key::F10 => KeyCode::F10,
+        Key::F11 => KeyCode::F11,
+        Key::F12 => KeyCode::F12,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// A menu of the organization's note templates; returns the one picked
+fn template_picker<'a>(ui: &mut egui::Ui, id: &str, templates: &'a [NoteTemplate]) -> Option<&'a NoteTemplate> {
+    if templates.is_empty() {
+        return None;
+    }
+    let mut picked = None;
+    egui::ComboBox::from_id_source(id).selected_text("Template").show_ui(ui, |ui| {
+        for template in templates {
+            if ui.selectable_label(false, template.name.as_str()).clicked() {
+                picked = Some(template);
+            }
+        }
+    });
+    picked
+}
+
+/// One command with its output so far
+fn command_entry(ui: &mut egui::Ui, execution: &CommandExecution) {
+    let finished = execution.exit_code.is_some() || execution.timed_out || execution.cancelled || execution.duration_ms > 0;
+    let outcome = match (execution.exit_code, execution.timed_out, execution.cancelled) {
+        _ if !finished => "running".to_string(),
+        (_, true, _) => "timed out".to_string(),
+        (_, _, true) => "cancelled".to_string(),
+        (Some(code), _, _) => format!("exit {} in {} ms", code, execution.duration_ms),
+        (None, _, _) => format!("ended in {} ms", execution.duration_ms),
+    };
+    ui.horizontal(|ui| {
+        ui.monospace(RichText::new(format!("> {}", execution.command)).strong());
+        ui.label(RichText::new(outcome).weak());
+    });
+    if !execution.stdout.is_empty() {
+        ui.monospace(execution.stdout.trim_end());
+    }
+    if !execution.stderr.is_empty() {
+        ui.label(RichText::new(execution.stderr.trim_end()).monospace().color(Color32::LIGHT_RED));
+    }
+    if execution.truncated {
+        ui.label(RichText::new(format!("Output cut at {} bytes", execution.max_length)).weak());
+    }
+    ui.separator();
+}
+
+impl eframe::App for SessionApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        while let Some(outcome) = self.bridge.try_outcome() {
+            self.view.apply(outcome);
+        }
+        self.update_texture(ctx);
+        let snapshot = self.bridge.snapshot();
+
+        // Closing the window ends the session, which may want a close-out
+        // note first
+        if ctx.input(|i| i.viewport().close_requested()) && !self.view.may_close(&snapshot) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            let request = self.view.request_close();
+            self.send(request);
+        }
+        if self.view.closing && snapshot.ended {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+        if self.view.closing && snapshot.closeout_required && !snapshot.ended {
+            self.closeout_prompt(ctx, &snapshot);
+        }
+        if let Some(action) = self.shortcuts.confirming() {
+            self.shortcut_prompt(ctx, &snapshot, action);
+        }
+
+        self.top_bar(ctx, &snapshot);
+        if self.view.show_toolbox {
+            self.toolbox(ctx, &snapshot);
+        }
+        self.transfers(ctx, &snapshot);
+        self.keyboard(ctx);
+        egui::CentralPanel::default().show(ctx, |ui| match self.view.tab {
+            SessionTab::Start => self.start_tab(ui),
+            SessionTab::General => Self::general_tab(ui, &snapshot),
+            SessionTab::Timeline => Self::timeline_tab(ui, &snapshot),
+            SessionTab::Messages => self.messages_tab(ui, &snapshot),
+            SessionTab::Commands => self.commands_tab(ui, &snapshot),
+            SessionTab::Notes => self.notes_tab(ui, &snapshot),
+        });
+
+        ctx.request_repaint_after(REPAINT_INTERVAL);
+    }
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, STATE_KEY, &self.view.state());
+    }
+}
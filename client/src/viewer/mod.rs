@@ -0,0 +1,64 @@
+//! Desktop session window for technicians
+//!
+//! An egui window over a `SessionWindow`: the tabs it models, the remote
+//! screen on the Start tab, the toolbox as a sidebar and the control bar.
+//! Everything the window does goes through `WindowBridge`, so the drawing
+//! code holds no session state of its own. Builds without the `viewer`
+//! feature fall back to the console session in `main.rs`. The end user's
+//! tray window lives in `tray`.
+
+mod app;
+mod screen;
+pub mod tray;
+
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::error::{GhostLinkError, Result};
+use crate::session::shortcuts::Platform;
+use crate::session::window_view::WindowBridge;
+use crate::session::SessionWindow;
+
+use app::SessionApp;
+use screen::RemoteScreen;
+
+/// Name the window's size and last tab are saved under
+const APP_ID: &str = "GhostLink Session";
+
+/// Show the session window until the technician closes it
+pub async fn run(window: SessionWindow) -> Result<()> {
+    let session_id = window.session_info.session_id.clone();
+    let screen = match RemoteScreen::connect(&window.server_url, &session_id, &window.auth_token) {
+        Ok(screen) => Some(screen),
+        Err(e) => {
+            warn!("No remote screen for session {}: {}", session_id, e);
+            None
+        }
+    };
+    // Shortcut tables follow the remote machine; an operating system it
+    // didn't report is taken to be this one's
+    let remote = Platform::from_os_name(&window.session_info.operating_system).unwrap_or_else(Platform::local);
+    if let Err(e) = window.refresh_note_templates().await {
+        warn!("No note templates for session {}: {}", session_id, e);
+    }
+    let bridge = WindowBridge::spawn(Arc::new(window)).await;
+
+    let options = eframe::NativeOptions {
+        viewport: eframe::egui::ViewportBuilder::default()
+            .with_title(format!("GhostLink - {}", session_id))
+            .with_inner_size([1280.0, 800.0])
+            .with_min_inner_size([640.0, 400.0]),
+        ..Default::default()
+    };
+
+    info!("Opening session window for {}", session_id);
+    // The UI owns this thread until the window closes; the bridge and the
+    // screen stream keep running on the runtime's workers
+    tokio::task::block_in_place(|| {
+        eframe::run_native(APP_ID, options, Box::new(move |cc| Box::new(SessionApp::new(cc, bridge, screen, remote))))
+    })
+    .map_err(|e| GhostLinkError::Other(format!("Session window failed: {}", e)))?;
+
+    info!("Session window for {} closed", session_id);
+    Ok(())
+}
@@ -0,0 +1,284 @@
+//! The remote screen on the Start tab
+//!
+//! The window attaches to the relay's viewer socket like the web viewer
+//! does and decodes frames in software: JPEG and PNG with `image`, tile
+//! frames painted over the previous picture, H.264 and H.265 through FFmpeg
+//! in builds with the `x264-encoder` feature. Only
+//! the newest picture is kept; a UI that falls behind skips frames rather
+//! than queueing them. Keyboard input goes back on the same socket as binary
+//! input batches.
+
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::capture::frame_protocol::{FrameMessage, VideoCodec};
+use crate::capture::tiles::TileFrame;
+use crate::connection::protocol::{encode_input_batch, InputEventBody};
+use crate::connection::proxy::ProxySettings;
+use crate::connection::tls;
+use crate::error::{ConnectionError, GhostLinkError, Result};
+use crate::input::InputEvent;
+use crate::recording::export::FrameCodec;
+
+/// A decoded picture of the remote screen
+pub struct ScreenFrame {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Shared {
+    frame: Option<ScreenFrame>,
+    status: String,
+}
+
+/// The stream of one session's screen, filled in by a background task
+#[derive(Clone)]
+pub struct RemoteScreen {
+    shared: Arc<Mutex<Shared>>,
+    input: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl RemoteScreen {
+    /// Attach to the session's screen; the session token is used up doing so
+    pub fn connect(server_url: &str, session_id: &str, token: &str) -> Result<Self> {
+        let url = viewer_url(server_url, session_id, token)?;
+        let shared = Arc::new(Mutex::new(Shared {
+            frame: None,
+            status: "Connecting to the remote screen".to_string(),
+        }));
+
+        let (input, input_rx) = mpsc::unbounded_channel();
+        let task_shared = Arc::clone(&shared);
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            let status = match stream(url, &task_shared, input_rx).await {
+                Ok(()) => "The remote screen stream ended".to_string(),
+                Err(e) => {
+                    warn!("Screen stream of session {} failed: {}", session_id, e);
+                    format!("Remote screen unavailable: {}", e)
+                }
+            };
+            task_shared.lock().status = status;
+        });
+
+        Ok(Self { shared, input })
+    }
+
+    /// The newest picture not yet taken
+    pub fn take_frame(&self) -> Option<ScreenFrame> {
+        self.shared.lock().frame.take()
+    }
+
+    /// What to show while there is no picture
+    pub fn status(&self) -> String {
+        self.shared.lock().status.clone()
+    }
+
+    /// Send input to the remote machine; dropped if the stream has ended
+    pub fn send_input(&self, events: &[InputEvent]) {
+        if !events.is_empty() {
+            let _ = self.input.send(input_batch(events));
+        }
+    }
+}
+
+/// Type the relay and agent know an input event by
+fn event_type(event: &InputEvent) -> &'static str {
+    match event {
+        InputEvent::MouseMove { .. } => "mouse_move",
+        InputEvent::MouseMoveRelative { .. } => "mouse_move_relative",
+        InputEvent::MouseButton { .. } => "mouse_button",
+        InputEvent::MouseScroll { .. } => "mouse_scroll",
+        InputEvent::KeyEvent { .. } => "key",
+        InputEvent::TextInput { .. } => "text",
+        InputEvent::SecureAttention => "secure_attention",
+    }
+}
+
+/// Binary input batch, as the web viewer sends it
+fn input_batch(events: &[InputEvent]) -> Vec<u8> {
+    let bodies: Vec<(&'static str, Vec<u8>)> = events
+        .iter()
+        .map(|event| (event_type(event), serde_json::to_vec(event).expect("input events serialize")))
+        .collect();
+    let events: Vec<InputEventBody<'_>> = bodies
+        .iter()
+        .map(|(event_type, data)| InputEventBody { event_type, data })
+        .collect();
+    encode_input_batch(&events)
+}
+
+/// The relay's viewer socket for a session, from the server's HTTP URL
+fn viewer_url(server_url: &str, session_id: &str, token: &str) -> Result<Url> {
+    let invalid = |reason: &str| ConnectionError::InvalidServerUrl {
+        url: server_url.to_string(),
+        reason: reason.to_string(),
+    };
+    let mut url = Url::parse(server_url).map_err(|e| invalid(&e.to_string()))?;
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        _ => return Err(invalid("expected an http(s) or ws(s) URL").into()),
+    };
+    url.set_scheme(scheme).map_err(|_| invalid("cannot be turned into a WebSocket URL"))?;
+    url.set_path("/api/ws");
+    url.query_pairs_mut()
+        .clear()
+        .append_pair("session_id", session_id)
+        .append_pair("token", token)
+        .append_pair("type", "viewer");
+    Ok(url)
+}
+
+async fn stream(url: Url, shared: &Mutex<Shared>, mut input: mpsc::UnboundedReceiver<Vec<u8>>) -> Result<()> {
+    let proxies = ProxySettings::current();
+    let (mut socket, _) = tls::open(&url, proxies.for_url(&url), None).await?;
+    info!("Attached to the remote screen");
+    shared.lock().status = "Waiting for the first frame".to_string();
+
+    let mut decoder = ScreenDecoder::default();
+    let mut reported = false;
+    loop {
+        let message = tokio::select! {
+            message = socket.next() => message,
+            Some(batch) = input.recv() => {
+                socket.send(Message::Binary(batch)).await?;
+                continue;
+            }
+        };
+        let Some(message) = message else { break };
+        match message? {
+            Message::Binary(data) => match decoder.decode(&data) {
+                Ok(Some(frame)) => shared.lock().frame = Some(frame),
+                Ok(None) => {}
+                // A codec this build cannot show fails every frame; say so once
+                Err(e) if !reported => {
+                    reported = true;
+                    shared.lock().status = e.to_string();
+                }
+                Err(e) => debug!("Dropped a screen frame: {}", e),
+            },
+            Message::Text(text) => decoder.configure(&text),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Turns viewer socket frames into pictures
+#[derive(Default)]
+struct ScreenDecoder {
+    /// Codec of frames that arrive without a frame header
+    bare_codec: Option<FrameCodec>,
+    /// The picture tile frames paint over
+    canvas: image::RgbaImage,
+    #[cfg(feature = "x264-encoder")]
+    video: Option<(FrameCodec, crate::recording::export::decode::FrameDecoder)>,
+}
+
+impl ScreenDecoder {
+    /// Pick up the codec from the agent's `StreamConfig`
+    fn configure(&mut self, text: &str) {
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(text) else { return };
+        if message["type"] != "StreamConfig" {
+            return;
+        }
+        self.bare_codec = match message["config"]["codec"].as_str() {
+            Some("jpeg") => Some(FrameCodec::Jpeg),
+            Some("png") => Some(FrameCodec::Png),
+            Some("h264") => Some(FrameCodec::H264),
+            Some("h265") => Some(FrameCodec::Hevc),
+            Some("tiles") => Some(FrameCodec::Tiles),
+            _ => None,
+        };
+    }
+
+    /// The picture in one binary message, or `None` while a video decoder
+    /// needs more input
+    fn decode(&mut self, message: &[u8]) -> Result<Option<ScreenFrame>> {
+        let (codec, data, size) = match FrameMessage::deserialize_binary(message) {
+            Ok(frame) => {
+                let info = frame.get_info();
+                let codec = match info.codec {
+                    VideoCodec::Raw => return raw_frame(info.width, info.height, frame.data).map(Some),
+                    VideoCodec::Png => FrameCodec::Png,
+                    VideoCodec::Jpeg => FrameCodec::Jpeg,
+                    VideoCodec::H264 | VideoCodec::NvencH264 => FrameCodec::H264,
+                    VideoCodec::H265 | VideoCodec::NvencH265 => FrameCodec::Hevc,
+                    VideoCodec::Tiles => FrameCodec::Tiles,
+                    VideoCodec::NvencAV1 => {
+                        return Err(GhostLinkError::Encode("AV1 streams cannot be shown in this window".to_string()));
+                    }
+                };
+                (codec, frame.data, Some((info.width, info.height)))
+            }
+            Err(_) => {
+                let codec = self.bare_codec
+                    .or_else(|| FrameCodec::detect(message))
+                    .ok_or_else(|| GhostLinkError::Protocol("Screen frame in an unknown format".to_string()))?;
+                (codec, message.to_vec(), None)
+            }
+        };
+
+        let Some(image) = self.decode_picture(codec, &data)? else {
+            return Ok(None);
+        };
+        let (width, height) = image.dimensions();
+        if size.is_some_and(|size| size != (width, height)) {
+            debug!("Frame header says {:?}, picture is {}x{}", size, width, height);
+        }
+        Ok(Some(ScreenFrame { width, height, rgba: image.into_raw() }))
+    }
+
+    fn decode_picture(&mut self, codec: FrameCodec, data: &[u8]) -> Result<Option<image::RgbaImage>> {
+        match codec {
+            FrameCodec::Jpeg | FrameCodec::Png => {
+                let image = image::load_from_memory(data)
+                    .map_err(|e| GhostLinkError::Encode(format!("Failed to decode {:?} frame: {}", codec, e)))?;
+                Ok(Some(image.to_rgba8()))
+            }
+            FrameCodec::Tiles => {
+                TileFrame::parse(data)?.composite(&mut self.canvas)?;
+                Ok(Some(self.canvas.clone()))
+            }
+            #[cfg(feature = "x264-encoder")]
+            FrameCodec::H264 | FrameCodec::Hevc => {
+                use crate::recording::export::decode::FrameDecoder;
+
+                // A codec change restarts decoding from the next keyframe
+                if self.video.as_ref().map(|(current, _)| *current) != Some(codec) {
+                    self.video = Some((codec, FrameDecoder::new(codec)?));
+                }
+                let (_, decoder) = self.video.as_mut().expect("decoder created above");
+                Ok(decoder.decode(data)?)
+            }
+            #[cfg(not(feature = "x264-encoder"))]
+            FrameCodec::H264 | FrameCodec::Hevc => Err(GhostLinkError::Encode(format!(
+                "{:?} streams need a build with the x264-encoder feature",
+                codec
+            ))),
+        }
+    }
+}
+
+/// An uncompressed frame; the header does not say its pixel order, so it is
+/// taken as RGBA
+fn raw_frame(width: u32, height: u32, data: Vec<u8>) -> Result<ScreenFrame> {
+    if data.len() != width as usize * height as usize * 4 {
+        return Err(GhostLinkError::Protocol(format!(
+            "Raw frame of {} bytes does not fit {}x{}",
+            data.len(),
+            width,
+            height
+        )));
+    }
+    Ok(ScreenFrame { width, height, rgba: data })
+}
@@ -0,0 +1,203 @@
+//! The end user's tray window over the agent's control API
+//!
+//! A small always-available window: whether the agent is connected, who
+//! has a session open and since when, and the actions policy allows. The
+//! drawing code only reads the last status; requests go to the agent from
+//! a task on the runtime.
+
+use chrono::Local;
+use eframe::egui::{self, Color32, RichText};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::info;
+
+use crate::control::{client, AgentStatus, ControlRequest, ControlResponse};
+use crate::error::{GhostLinkError, Result};
+
+/// Name the window's size is saved under
+const APP_ID: &str = "GhostLink";
+
+/// How often the status is asked for
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Show the tray window until the end user closes it
+pub async fn run() -> Result<()> {
+    let bridge = TrayBridge::spawn();
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_title("GhostLink")
+            .with_inner_size([360.0, 420.0])
+            .with_min_inner_size([300.0, 240.0]),
+        ..Default::default()
+    };
+
+    info!("Opening tray window");
+    tokio::task::block_in_place(|| eframe::run_native(APP_ID, options, Box::new(move |_| Box::new(TrayApp::new(bridge)))))
+        .map_err(|e| GhostLinkError::Other(format!("Tray window failed: {}", e)))
+}
+
+/// The UI's side of the agent connection
+struct TrayBridge {
+    requests: mpsc::UnboundedSender<ControlRequest>,
+    outcomes: mpsc::UnboundedReceiver<String>,
+    status: watch::Receiver<std::result::Result<AgentStatus, String>>,
+}
+
+impl TrayBridge {
+    /// Ask the agent for its status until the bridge is dropped, and send
+    /// it the end user's requests in between
+    fn spawn() -> Self {
+        let (requests, mut request_rx) = mpsc::unbounded_channel();
+        let (outcome_tx, outcomes) = mpsc::unbounded_channel();
+        let (status_tx, status) = watch::channel(Err("Looking for the GhostLink agent...".to_string()));
+
+        tokio::spawn(async move {
+            let mut refresh = tokio::time::interval(STATUS_INTERVAL);
+            loop {
+                tokio::select! {
+                    request = request_rx.recv() => {
+                        let Some(request) = request else { break };
+                        let outcome = match client::request(&request).await {
+                            Ok(response) => describe(&request, response),
+                            Err(e) => e.to_string(),
+                        };
+                        let _ = outcome_tx.send(outcome);
+                    }
+                    _ = refresh.tick() => {}
+                }
+                let current = match client::request(&ControlRequest::Status).await {
+                    Ok(ControlResponse::Status { status }) => Ok(status),
+                    Ok(other) => Err(format!("Unexpected answer from the agent: {:?}", other)),
+                    Err(e) => Err(e.to_string()),
+                };
+                if status_tx.send(current).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { requests, outcomes, status }
+    }
+}
+
+/// What the end user is told about the answer to `request`
+fn describe(request: &ControlRequest, response: ControlResponse) -> String {
+    match (request, response) {
+        (_, ControlResponse::Error { message }) => message,
+        (_, ControlResponse::Joined { code, expires_at }) => {
+            format!("Joined support code {}; your technician can connect until {}", code, expires_at.with_timezone(&Local).format("%H:%M"))
+        }
+        (ControlRequest::Disconnect { .. }, _) => "Session ended".to_string(),
+        (ControlRequest::Pause { hours }, _) => format!("Remote access paused for {} hours", hours),
+        (ControlRequest::Resume, _) => "Remote access resumed".to_string(),
+        (_, _) => "Done".to_string(),
+    }
+}
+
+struct TrayApp {
+    bridge: TrayBridge,
+    pause_hours: u32,
+    code: String,
+    message: Option<String>,
+}
+
+impl TrayApp {
+    fn new(bridge: TrayBridge) -> Self {
+        Self { bridge, pause_hours: 1, code: String::new(), message: None }
+    }
+
+    fn send(&self, request: ControlRequest) {
+        // The bridge only stops once this side is dropped
+        let _ = self.bridge.requests.send(request);
+    }
+
+    fn sessions(&self, ui: &mut egui::Ui, status: &AgentStatus) {
+        ui.heading("Sessions");
+        if status.sessions.is_empty() {
+            ui.label("Nobody is connected to this computer.");
+        }
+        for session in &status.sessions {
+            ui.horizontal(|ui| {
+                let technician = session.technician.as_deref().unwrap_or("A technician");
+                let since = session.started_at.with_timezone(&Local).format("%H:%M");
+                ui.label(format!("{} ({}), since {}", technician, session.session_type, since));
+                let button = ui.add_enabled(session.may_disconnect, egui::Button::new("Disconnect"));
+                let button = button.on_disabled_hover_text("Your organization does not allow ending this session");
+                if button.clicked() {
+                    self.send(ControlRequest::Disconnect { session_id: session.session_id.clone() });
+                }
+            });
+        }
+    }
+
+    fn pause(&mut self, ui: &mut egui::Ui, status: &AgentStatus) {
+        ui.heading("Remote access");
+        if let Some(until) = status.paused_until {
+            ui.label(format!("Paused until {}", until.with_timezone(&Local).format("%a %H:%M")));
+            if ui.button("Resume").clicked() {
+                self.send(ControlRequest::Resume);
+            }
+            return;
+        }
+        let Some(max) = status.max_pause_hours else {
+            ui.label("Your organization does not allow pausing remote access.");
+            return;
+        };
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.pause_hours).clamp_range(1..=max).suffix(" h"));
+            if ui.button("Pause").on_hover_text("Ends running sessions and refuses new ones").clicked() {
+                self.send(ControlRequest::Pause { hours: self.pause_hours });
+            }
+        });
+    }
+
+    fn get_support(&mut self, ui: &mut egui::Ui, status: &AgentStatus) {
+        ui.heading("Get support");
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.code).hint_text("Code from your technician").desired_width(160.0));
+            let ready = !self.code.trim().is_empty() && status.connected && status.paused_until.is_none();
+            if ui.add_enabled(ready, egui::Button::new("Join")).clicked() {
+                self.send(ControlRequest::Join { code: std::mem::take(&mut self.code) });
+            }
+        });
+    }
+}
+
+impl eframe::App for TrayApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        while let Ok(outcome) = self.bridge.outcomes.try_recv() {
+            self.message = Some(outcome);
+        }
+        let status = self.bridge.status.borrow().clone();
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let status = match status {
+                Ok(status) => status,
+                Err(e) => {
+                    ui.colored_label(Color32::LIGHT_RED, e);
+                    return;
+                }
+            };
+            let (state, color) = match (status.paused_until, status.connected) {
+                (Some(_), _) => ("access paused", Color32::YELLOW),
+                (None, true) => ("connected", Color32::LIGHT_GREEN),
+                (None, false) => ("not connected", Color32::LIGHT_RED),
+            };
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(&status.hostname).strong());
+                ui.colored_label(color, state);
+            });
+            ui.separator();
+            self.sessions(ui, &status);
+            ui.separator();
+            self.pause(ui, &status);
+            ui.separator();
+            self.get_support(ui, &status);
+            if let Some(message) = &self.message {
+                ui.separator();
+                ui.label(message);
+            }
+        });
+        ctx.request_repaint_after(STATUS_INTERVAL / 2);
+    }
+}
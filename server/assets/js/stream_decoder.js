@@ -0,0 +1,213 @@
+// Screen stream decoding for the session viewer
+//
+// Video frames go through a WebCodecs VideoDecoder and each decoded frame is
+// drawn to the canvas. JPEG and PNG frames, and browsers without WebCodecs,
+// use createImageBitmap instead. Tile frames paint the changed regions of
+// the screen over the canvas, so they are painted strictly in the order they
+// arrive. The canvas keeps the stream's resolution; CSS scales it to the page.
+
+// Frames queued in the decoder before delta frames are dropped
+const MAX_DECODE_QUEUE = 8;
+// Images decoding at once before further images are dropped
+const MAX_PENDING_IMAGES = 2;
+// Tile frames waiting to be painted before delta frames are dropped
+const MAX_PENDING_TILE_FRAMES = 4;
+
+export class StreamDecoder {
+  constructor(canvas, onFrame, onError) {
+    this.canvas = canvas;
+    this.context = canvas.getContext("2d");
+    this.onFrame = onFrame;
+    this.onError = onError;
+    this.codec = null;
+    this.decoder = null;
+    this.needKeyframe = true;
+    this.pendingImages = 0;
+    this.pendingTileFrames = 0;
+    // Settles once the last tile frame queued is painted
+    this.tilesPainted = Promise.resolve();
+    // Bumped when the stream changes, so tiles of the old one are not painted
+    this.generation = 0;
+  }
+
+  static webCodecsSupported() {
+    return typeof VideoDecoder !== "undefined";
+  }
+
+  // Whether `codec`, a WebCodecs codec string, decodes in this browser
+  static async supports(codec) {
+    if (!StreamDecoder.webCodecsSupported()) {
+      return false;
+    }
+    try {
+      const support = await VideoDecoder.isConfigSupported({ codec, optimizeForLatency: true });
+      return support.supported === true;
+    } catch {
+      return false;
+    }
+  }
+
+  // Prepare for a stream of `codec` at `width`x`height`; false when the
+  // browser cannot decode it
+  configure(codec, width, height) {
+    this.close();
+    this.canvas.width = width;
+    this.canvas.height = height;
+    this.codec = codec;
+    this.needKeyframe = true;
+    if (codec.startsWith("image/") || codec === "tiles") {
+      return true;
+    }
+    if (!StreamDecoder.webCodecsSupported()) {
+      return false;
+    }
+    return this.openDecoder();
+  }
+
+  openDecoder() {
+    try {
+      this.decoder = new VideoDecoder({
+        output: (frame) => this.draw(frame),
+        error: (error) => this.fail(`Decoder error: ${error.message}`),
+      });
+      this.decoder.configure({
+        codec: this.codec,
+        codedWidth: this.canvas.width,
+        codedHeight: this.canvas.height,
+        optimizeForLatency: true,
+      });
+      return true;
+    } catch (error) {
+      this.decoder = null;
+      this.fail(`Cannot decode ${this.codec}: ${error.message}`);
+      return false;
+    }
+  }
+
+  // Queue one frame; false when it was dropped instead
+  decode(data, keyframe, timestamp) {
+    if (this.codec === null) {
+      return false;
+    }
+    if (this.codec.startsWith("image/")) {
+      return this.decodeImage(data);
+    }
+    if (this.decoder === null || this.decoder.state === "closed") {
+      // A failed decoder restarts at the next keyframe
+      if (!keyframe || !this.openDecoder()) {
+        return false;
+      }
+      this.needKeyframe = true;
+    }
+    if (this.needKeyframe && !keyframe) {
+      return false;
+    }
+    if (!keyframe && this.decoder.decodeQueueSize > MAX_DECODE_QUEUE) {
+      // Behind: skip to the next keyframe rather than fall further back
+      this.needKeyframe = true;
+      return false;
+    }
+    try {
+      this.decoder.decode(new EncodedVideoChunk({
+        type: keyframe ? "key" : "delta",
+        timestamp,
+        data,
+      }));
+      this.needKeyframe = false;
+      return true;
+    } catch (error) {
+      this.fail(`Decode failed: ${error.message}`);
+      return false;
+    }
+  }
+
+  decodeImage(data) {
+    if (this.pendingImages >= MAX_PENDING_IMAGES) {
+      return false;
+    }
+    this.pendingImages += 1;
+    createImageBitmap(new Blob([data], { type: this.codec }))
+      .then((bitmap) => this.draw(bitmap))
+      .catch((error) => this.fail(`Image decode failed: ${error.message}`))
+      .finally(() => { this.pendingImages -= 1; });
+    return true;
+  }
+
+  // Queue one tile frame of `width`x`height`; its images decode in parallel
+  // with earlier frames, but it is painted only after them. False when it
+  // was dropped instead.
+  drawTiles(tiles, width, height, keyframe) {
+    if (this.codec !== "tiles" || (this.needKeyframe && !keyframe)) {
+      return false;
+    }
+    if (!keyframe && this.pendingTileFrames >= MAX_PENDING_TILE_FRAMES) {
+      // Behind: a skipped frame leaves stale regions, so skip to the next
+      // full frame
+      this.needKeyframe = true;
+      return false;
+    }
+    this.needKeyframe = false;
+    this.pendingTileFrames += 1;
+    const generation = this.generation;
+    const pictures = Promise.all(tiles.map((tile) =>
+      tile.type === undefined ? null : createImageBitmap(new Blob([tile.data], { type: tile.type }))));
+    this.tilesPainted = this.tilesPainted
+      .then(() => pictures)
+      .then((bitmaps) => {
+        if (generation === this.generation) {
+          this.paintTiles(tiles, bitmaps, width, height);
+        }
+        bitmaps.forEach((bitmap) => bitmap?.close());
+      })
+      .catch((error) => this.fail(`Tile decode failed: ${error.message}`))
+      .finally(() => { this.pendingTileFrames -= 1; });
+    return true;
+  }
+
+  paintTiles(tiles, bitmaps, width, height) {
+    if (this.canvas.width !== width || this.canvas.height !== height) {
+      this.canvas.width = width;
+      this.canvas.height = height;
+    }
+    tiles.forEach((tile, index) => {
+      const bitmap = bitmaps[index];
+      if (bitmap === null) {
+        this.context.fillStyle = tile.color;
+        this.context.fillRect(tile.x, tile.y, tile.width, tile.height);
+      } else {
+        this.context.drawImage(bitmap, tile.x, tile.y);
+      }
+    });
+    this.onFrame();
+  }
+
+  draw(picture) {
+    const width = picture.displayWidth ?? picture.width;
+    const height = picture.displayHeight ?? picture.height;
+    if (this.canvas.width !== width || this.canvas.height !== height) {
+      this.canvas.width = width;
+      this.canvas.height = height;
+    }
+    this.context.drawImage(picture, 0, 0);
+    picture.close();
+    this.onFrame();
+  }
+
+  fail(message) {
+    if (this.decoder !== null && this.decoder.state !== "closed") {
+      this.decoder.close();
+    }
+    this.decoder = null;
+    this.needKeyframe = true;
+    this.onError(message);
+  }
+
+  close() {
+    if (this.decoder !== null && this.decoder.state !== "closed") {
+      this.decoder.close();
+    }
+    this.decoder = null;
+    this.codec = null;
+    this.generation += 1;
+  }
+}
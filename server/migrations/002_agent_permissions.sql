@@ -0,0 +1,13 @@
+-- Per-agent access grants for non-admin users
+CREATE TABLE permissions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    agent_id UUID NOT NULL REFERENCES agents(id) ON DELETE CASCADE,
+    can_view BOOLEAN NOT NULL DEFAULT true,
+    can_control BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE(user_id, agent_id)
+);
+
+CREATE INDEX idx_permissions_user ON permissions(user_id);
+CREATE INDEX idx_permissions_agent ON permissions(agent_id);
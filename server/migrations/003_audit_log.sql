@@ -0,0 +1,16 @@
+-- Server-wide audit trail for privileged actions (PAM, terminal, toolbox)
+-- Rows carry plain IDs rather than foreign keys so entries outlive the
+-- agents, sessions and users they mention
+CREATE TABLE audit_log (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    category VARCHAR(50) NOT NULL, -- 'pam', 'terminal', 'toolbox'
+    action VARCHAR(100) NOT NULL,
+    actor VARCHAR(255), -- User ID or name as reported by the subsystem
+    session_id UUID,
+    details JSONB DEFAULT '{}'
+);
+
+CREATE INDEX idx_audit_log_timestamp ON audit_log(timestamp);
+CREATE INDEX idx_audit_log_category ON audit_log(category);
+CREATE INDEX idx_audit_log_session ON audit_log(session_id);
@@ -0,0 +1,18 @@
+-- Groups and free-form tags for organizing devices
+CREATE TABLE device_groups (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    description TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE(organization_id, name)
+);
+
+-- Deleting a group leaves its devices ungrouped
+ALTER TABLE agents
+    ADD COLUMN group_id UUID REFERENCES device_groups(id) ON DELETE SET NULL,
+    ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX idx_agents_group ON agents(group_id);
+CREATE INDEX idx_agents_tags ON agents USING GIN (tags);
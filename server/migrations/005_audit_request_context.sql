@@ -0,0 +1,10 @@
+-- Who an audit entry concerns and where the request came from, so the trail
+-- can be searched by agent and traced back to a client
+ALTER TABLE audit_log
+    ADD COLUMN agent_id UUID,
+    ADD COLUMN source_ip VARCHAR(64),
+    ADD COLUMN user_agent TEXT;
+
+CREATE INDEX idx_audit_log_agent ON audit_log(agent_id);
+CREATE INDEX idx_audit_log_actor ON audit_log(actor);
+CREATE INDEX idx_audit_log_action ON audit_log(action);
@@ -0,0 +1,4 @@
+-- Short names technicians reach devices by instead of their IDs
+ALTER TABLE agents ADD COLUMN alias VARCHAR(32);
+
+CREATE UNIQUE INDEX idx_agents_alias ON agents(alias);
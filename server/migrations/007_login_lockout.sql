@@ -0,0 +1,3 @@
+-- Failed logins in a row and the lockout they earned, so throttling survives restarts
+ALTER TABLE users ADD COLUMN failed_login_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN locked_until TIMESTAMPTZ;
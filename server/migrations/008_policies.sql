@@ -0,0 +1,14 @@
+-- Client policies for an organization, a device group or a single device.
+-- target_id has no foreign key since it points at a different table per scope;
+-- NULL with the organization scope covers agents in no organization.
+CREATE TABLE policies (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    scope VARCHAR(32) NOT NULL CHECK (scope IN ('organization', 'group', 'device')),
+    target_id UUID,
+    document JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX idx_policies_target
+    ON policies(scope, COALESCE(target_id, '00000000-0000-0000-0000-000000000000'));
@@ -0,0 +1,12 @@
+-- Users created on their first OIDC login are found again by the IdP's subject
+ALTER TABLE users ADD COLUMN oidc_subject VARCHAR(255) UNIQUE;
+
+-- Device groups whose devices a user may reach, granted by OIDC claim mappings
+CREATE TABLE user_device_groups (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    group_id UUID NOT NULL REFERENCES device_groups(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, group_id)
+);
+
+CREATE INDEX idx_user_device_groups_group ON user_device_groups(group_id);
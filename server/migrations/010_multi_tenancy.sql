@@ -0,0 +1,25 @@
+-- Policies are listed per organization, so each remembers the organization
+-- of its target
+ALTER TABLE policies
+    ADD COLUMN organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE;
+
+UPDATE policies SET organization_id = target_id
+    WHERE scope = 'organization' AND target_id IN (SELECT id FROM organizations);
+UPDATE policies p SET organization_id = g.organization_id
+    FROM device_groups g WHERE p.scope = 'group' AND g.id = p.target_id;
+UPDATE policies p SET organization_id = a.organization_id
+    FROM agents a WHERE p.scope = 'device' AND a.id = p.target_id;
+
+CREATE INDEX idx_policies_organization ON policies(organization_id);
+
+-- A session belongs to the organization of its agent, and follows it
+UPDATE sessions s SET organization_id = a.organization_id
+    FROM agents a WHERE s.agent_id = a.id AND s.organization_id IS DISTINCT FROM a.organization_id;
+
+ALTER TABLE agents ADD CONSTRAINT agents_id_organization UNIQUE (id, organization_id);
+ALTER TABLE sessions ADD CONSTRAINT sessions_agent_organization
+    FOREIGN KEY (agent_id, organization_id) REFERENCES agents(id, organization_id) ON UPDATE CASCADE;
+
+-- Devices and groups are listed per organization
+CREATE INDEX idx_device_groups_organization ON device_groups(organization_id);
+CREATE INDEX idx_user_organizations_organization ON user_organizations(organization_id);
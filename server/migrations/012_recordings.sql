@@ -0,0 +1,28 @@
+-- Session recordings uploaded by agents. The chunks themselves live in the
+-- recording store; chunks lists each one's size, SHA-256 and whether it has
+-- arrived. finished marks a recording whose agent declared its last chunk.
+-- Nothing here cascades: a row goes with its chunks, when retention says so.
+CREATE TABLE recordings (
+    id UUID PRIMARY KEY,
+    session_id UUID NOT NULL,
+    agent_id UUID NOT NULL,
+    organization_id UUID,
+    technician_id UUID,
+    source_id VARCHAR(128) NOT NULL,
+    content_type VARCHAR(128) NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    ended_at TIMESTAMPTZ,
+    status VARCHAR(16) NOT NULL CHECK (status IN ('uploading', 'complete')),
+    finished BOOLEAN NOT NULL DEFAULT FALSE,
+    size BIGINT NOT NULL DEFAULT 0,
+    chunks JSONB NOT NULL DEFAULT '[]',
+    metadata JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (agent_id, session_id, source_id)
+);
+
+CREATE INDEX idx_recordings_session ON recordings(session_id);
+CREATE INDEX idx_recordings_agent ON recordings(agent_id, started_at);
+CREATE INDEX idx_recordings_technician ON recordings(technician_id, started_at);
+CREATE INDEX idx_recordings_organization ON recordings(organization_id, started_at);
@@ -0,0 +1,57 @@
+-- Notes, custom fields and inventory technicians keep per device. A note is
+-- kept as revisions, the newest being the current note. Inventory holds the
+-- agent's latest report; what changed between reports goes to
+-- device_inventory_changes. Device rows are not referenced, since offline
+-- and temporary agents may never have been stored.
+CREATE TABLE device_notes (
+    id UUID PRIMARY KEY,
+    agent_id UUID NOT NULL,
+    body TEXT NOT NULL,
+    author_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    author_email VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_device_notes_agent ON device_notes(agent_id, created_at);
+
+-- Fields an organization defines for its devices; options lists the values
+-- of an enum field
+CREATE TABLE custom_fields (
+    id UUID PRIMARY KEY,
+    organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE,
+    key VARCHAR(64) NOT NULL,
+    label VARCHAR(255) NOT NULL,
+    field_type VARCHAR(16) NOT NULL CHECK (field_type IN ('text', 'number', 'date', 'enum')),
+    options JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX idx_custom_fields_key ON custom_fields(COALESCE(organization_id, '00000000-0000-0000-0000-000000000000'), key);
+
+CREATE TABLE device_field_values (
+    agent_id UUID NOT NULL,
+    field_id UUID NOT NULL REFERENCES custom_fields(id) ON DELETE CASCADE,
+    value JSONB NOT NULL,
+    updated_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (agent_id, field_id)
+);
+
+CREATE TABLE device_inventory (
+    agent_id UUID PRIMARY KEY,
+    inventory JSONB NOT NULL,
+    collected_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE device_inventory_changes (
+    id UUID PRIMARY KEY,
+    agent_id UUID NOT NULL,
+    field VARCHAR(255) NOT NULL,
+    previous JSONB NOT NULL,
+    current JSONB NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_device_inventory_changes_agent ON device_inventory_changes(agent_id, changed_at);
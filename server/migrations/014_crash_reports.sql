@@ -0,0 +1,25 @@
+-- Crash reports agents upload after a panic or fatal signal. report holds
+-- the whole report as the agent sent it; group_key is the frame reports are
+-- grouped by, usually the topmost frame of GhostLink's own code. Device rows
+-- are not referenced, since temporary agents may never have been stored.
+CREATE TABLE crash_reports (
+    id UUID PRIMARY KEY,
+    agent_id UUID NOT NULL,
+    organization_id UUID,
+    -- The agent's ID for the report, so a retried upload is stored once
+    report_id UUID NOT NULL,
+    version VARCHAR(64) NOT NULL,
+    os VARCHAR(64) NOT NULL,
+    kind VARCHAR(16) NOT NULL CHECK (kind IN ('panic', 'signal')),
+    message TEXT NOT NULL,
+    group_key TEXT NOT NULL,
+    report JSONB NOT NULL,
+    size BIGINT NOT NULL,
+    crashed_at TIMESTAMPTZ NOT NULL,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (agent_id, report_id)
+);
+
+CREATE INDEX idx_crash_reports_agent ON crash_reports(agent_id, crashed_at);
+CREATE INDEX idx_crash_reports_organization ON crash_reports(organization_id, crashed_at);
+CREATE INDEX idx_crash_reports_group ON crash_reports(group_key);
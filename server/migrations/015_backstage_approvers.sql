@@ -0,0 +1,3 @@
+-- Users who may approve held backstage sessions besides admins. Kept in
+-- line with the OIDC claim mappings on every login.
+ALTER TABLE users ADD COLUMN is_approver BOOLEAN NOT NULL DEFAULT FALSE;
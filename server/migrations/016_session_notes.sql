@@ -0,0 +1,37 @@
+-- Notes technicians keep on sessions, and the templates an organization
+-- offers for them. history holds what a note said before each edit, oldest
+-- first. Session rows are not referenced, since sessions of a server
+-- without a database before are never stored.
+CREATE TABLE note_templates (
+    id UUID PRIMARY KEY,
+    organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE,
+    name VARCHAR(100) NOT NULL,
+    body TEXT NOT NULL,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX idx_note_templates_name ON note_templates(COALESCE(organization_id, '00000000-0000-0000-0000-000000000000'), LOWER(name));
+
+CREATE TABLE session_notes (
+    id UUID PRIMARY KEY,
+    session_id UUID NOT NULL,
+    organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE,
+    body TEXT NOT NULL,
+    template_id UUID REFERENCES note_templates(id) ON DELETE SET NULL,
+    private BOOLEAN NOT NULL DEFAULT FALSE,
+    closeout BOOLEAN NOT NULL DEFAULT FALSE,
+    author_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    author_email VARCHAR(255),
+    history JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_session_notes_session ON session_notes(session_id, created_at);
+
+-- Ticket in the organization's PSA a session belongs to
+ALTER TABLE sessions ADD COLUMN ticket_ref VARCHAR(128);
+
+CREATE INDEX idx_sessions_ticket_ref ON sessions(ticket_ref) WHERE ticket_ref IS NOT NULL;
@@ -1,18 +1,39 @@
 use axum::{
-    extract::{Path, Query, State, WebSocketUpgrade},
-    http::StatusCode,
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket},
+        Path, Query, State, WebSocketUpgrade,
+    },
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde::Deserialize;
 use std::collections::HashMap;
 use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use tracing::warn;
 use crate::{
-    device_manager::{SessionRequest, DeviceRegistration},
-    models::SessionType,
+    approvals::{self, HeldSession},
+    audit::RequestContext,
+    auth::{access_grants, authz, jwt::{error, AuthUser}, session_tokens::{self, SessionScope}},
+    device_groups::{self, DeviceFilter, DeviceSort, GroupFilter, SortOrder},
+    device_manager::{SessionFilter, SessionRequest, DeviceRegistration},
+    models::{AuditLog, SessionCommand, SessionType},
+    organizations::{self, Tenant},
+    rate_limit::{ClientAddress, ConnectionPermit, ConnectionRefused},
+    relay::bandwidth,
+    relay::capabilities::AgentCapability,
+    relay::codecs::ViewerCapabilities,
+    session_events::SessionEvent,
+    session_notes,
     AppState,
 };
 
+/// Output a session command may carry, stdout and stderr together; the
+/// Commands tab's `#maxlength` stops at the same size
+const MAX_COMMAND_OUTPUT: usize = 1024 * 1024;
+
 /// Health check endpoint
 pub async fn health_check() -> impl IntoResponse {
     Json(serde_json::json!({
@@ -22,31 +43,150 @@ pub async fn health_check() -> impl IntoResponse {
     }))
 }
 
-/// Get all connected devices
+/// Devices returned per page when no limit is given
+const DEFAULT_DEVICE_PAGE: usize = 100;
+
+/// Largest page of devices a caller may request
+const MAX_DEVICE_PAGE: usize = 1000;
+
+/// Filters, sorting and pagination for listing devices
+#[derive(Debug, Deserialize)]
+pub struct DeviceListQuery {
+    /// Group ID, or `none` for devices in no group
+    pub group: Option<String>,
+    /// Comma-separated tags the device must all carry
+    pub tag: Option<String>,
+    pub online: Option<bool>,
+    pub platform: Option<String>,
+    /// Text to find in the name or hostname
+    pub q: Option<String>,
+    #[serde(default)]
+    pub sort: DeviceSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// List connected and offline devices of the caller's organization, each
+/// with whether it is online; guests see only the device of their grant
 pub async fn api_get_devices(
     State(app_state): State<AppState>,
-) -> impl IntoResponse {
-    let devices = app_state.device_manager.get_connected_devices().await;
+    user: AuthUser,
+    tenant: Tenant,
+    Query(query): Query<DeviceListQuery>,
+) -> Response {
+    let grant = match access_grants::guest_grant(&app_state, &user).await {
+        Ok(grant) => grant,
+        Err(e) => return e.into_response(),
+    };
+    let group = match query.group.as_deref().map(str::parse::<GroupFilter>).transpose() {
+        Ok(group) => group,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let tags: Vec<String> = query.tag.as_deref()
+        .map(|tags| tags.split(',').map(str::to_string).collect())
+        .unwrap_or_default();
+    let tags = match device_groups::normalize_tags(&tags) {
+        Ok(tags) => tags,
+        Err(e) => return e.into_response(),
+    };
+    let filter = DeviceFilter {
+        group,
+        tags,
+        online: query.online,
+        platform: query.platform.filter(|platform| !platform.is_empty()),
+        query: query.q.map(|q| q.trim().to_string()).filter(|q| !q.is_empty()),
+        tenant,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_DEVICE_PAGE).min(MAX_DEVICE_PAGE);
+
+    let mut devices = app_state.device_manager.list_devices(&filter).await;
+    if let Some(grant) = grant {
+        devices.retain(|device| device.agent.id == grant.agent_id);
+    }
+    device_groups::sort_devices(&mut devices, query.sort, query.order);
+    let total = devices.len();
+    let page: Vec<_> = devices.into_iter().skip(query.offset).take(limit).collect();
+
     Json(serde_json::json!({
-        "devices": devices
-    }))
+        "devices": page,
+        "total": total,
+        "limit": limit,
+        "offset": query.offset
+    })).into_response()
 }
 
-/// Get device statistics
+/// Get a single device, including whether it is currently connected
+pub async fn api_get_device(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let agent_uuid = match Uuid::parse_str(&agent_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                "error": "Invalid agent ID format"
+            }))).into_response();
+        }
+    };
+
+    let grant = match access_grants::guest_grant(&app_state, &user).await {
+        Ok(grant) => grant,
+        Err(e) => return e.into_response(),
+    };
+    let device = app_state.device_manager.get_agent(agent_uuid).await
+        .filter(|(agent, _)| tenant.includes(agent.organization_id))
+        .filter(|(agent, _)| grant.as_ref().is_none_or(|grant| grant.agent_id == agent.id));
+    match device {
+        Some((agent, online)) => {
+            let metrics = app_state.device_manager.latest_metrics(agent_uuid).await;
+            let mut detail = serde_json::json!({
+                "device": agent,
+                "online": online,
+                "metrics": metrics
+            });
+            // Notes and fields are the organization's, not the guest's
+            if grant.is_none() {
+                let records = &app_state.device_manager.device_records;
+                detail["note"] = serde_json::json!(records.note(agent_uuid).await);
+                detail["fields"] = serde_json::json!(records.device_fields(&agent).await);
+                detail["inventory"] = serde_json::json!(records.inventory(agent_uuid).await);
+                detail["crashes"] = serde_json::json!(app_state.device_manager.crash_reports.summary(agent_uuid).await);
+            }
+            Json(detail).into_response()
+        },
+        None => {
+            (StatusCode::NOT_FOUND, Json(serde_json::json!({
+                "error": format!("Device not found: {}", agent_uuid)
+            }))).into_response()
+        }
+    }
+}
+
+/// Get device statistics for the caller's organization
 pub async fn api_get_stats(
     State(app_state): State<AppState>,
+    tenant: Tenant,
 ) -> impl IntoResponse {
-    let stats = app_state.device_manager.get_stats().await;
+    let stats = app_state.device_manager.get_stats(tenant).await;
     Json(stats)
 }
 
 /// Get sessions for a specific device
 pub async fn api_get_device_sessions(
     State(app_state): State<AppState>,
+    tenant: Tenant,
     Path(agent_id): Path<String>,
 ) -> Response {
     match Uuid::parse_str(&agent_id) {
         Ok(agent_uuid) => {
+            if !in_tenant(&app_state, tenant, agent_uuid).await {
+                return organizations::device_not_found(agent_uuid).into_response();
+            }
             let sessions = app_state.device_manager.get_device_sessions(agent_uuid).await;
             Json(serde_json::json!({
                 "sessions": sessions
@@ -60,20 +200,383 @@ pub async fn api_get_device_sessions(
     }
 }
 
+/// Whether `agent_id` is a known device of `tenant`
+pub(crate) async fn in_tenant(app_state: &AppState, tenant: Tenant, agent_id: Uuid) -> bool {
+    app_state.device_manager.get_agent(agent_id).await
+        .is_some_and(|(agent, _)| tenant.includes(agent.organization_id))
+}
+
+/// Whether `session_id` is an active or recent session of `tenant`
+pub(crate) async fn session_in_tenant(app_state: &AppState, tenant: Tenant, session_id: Uuid) -> bool {
+    app_state.device_manager.get_session(session_id).await
+        .is_some_and(|session| tenant.includes(session.organization_id))
+}
+
+/// History returned when no window is given
+const DEFAULT_METRICS_MINUTES: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct MetricsQuery {
+    pub minutes: Option<i64>,
+}
+
+/// Heartbeat metrics of a device over the last `minutes`, oldest first
+pub async fn api_get_device_metrics(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(agent_id): Path<String>,
+    Query(query): Query<MetricsQuery>,
+) -> Response {
+    let agent_uuid = match Uuid::parse_str(&agent_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                "error": "Invalid agent ID format"
+            }))).into_response();
+        }
+    };
+    if !in_tenant(&app_state, tenant, agent_uuid).await {
+        return organizations::device_not_found(agent_uuid).into_response();
+    }
+
+    let minutes = query.minutes.unwrap_or(DEFAULT_METRICS_MINUTES).max(1);
+    let since = Utc::now() - chrono::Duration::minutes(minutes);
+    let samples = app_state.device_manager.metrics_history(agent_uuid, since).await;
+    Json(serde_json::json!({
+        "agent_id": agent_uuid,
+        "minutes": minutes,
+        "samples": samples
+    })).into_response()
+}
+
+/// Sessions returned per page when no limit is given
+const DEFAULT_SESSION_PAGE: usize = 50;
+
+/// Largest page of sessions a caller may request
+const MAX_SESSION_PAGE: usize = 500;
+
+/// Filters and pagination for listing sessions
+#[derive(Debug, Deserialize)]
+pub struct SessionListQuery {
+    pub agent_id: Option<Uuid>,
+    pub status: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// List active and recently ended sessions of the caller's organization,
+/// newest first
+pub async fn api_list_sessions(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<SessionListQuery>,
+) -> impl IntoResponse {
+    let filter = SessionFilter {
+        agent_id: query.agent_id,
+        status: query.status,
+        since: query.since,
+        tenant,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_SESSION_PAGE).min(MAX_SESSION_PAGE);
+
+    let sessions = app_state.device_manager.list_sessions(&filter).await;
+    let total = sessions.len();
+    let page: Vec<_> = sessions.into_iter().skip(query.offset).take(limit).collect();
+
+    Json(serde_json::json!({
+        "sessions": page,
+        "total": total,
+        "limit": limit,
+        "offset": query.offset
+    }))
+}
+
+/// Get a single active or recently ended session
+pub async fn api_get_session(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_uuid = match Uuid::parse_str(&session_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                "error": "Invalid session ID format"
+            }))).into_response();
+        }
+    };
+
+    let session = app_state.device_manager.get_session(session_uuid).await
+        .filter(|session| tenant.includes(session.organization_id));
+    match session {
+        Some(session) => {
+            // Ended sessions no longer take part in control
+            let control = match session.status.as_str() {
+                "ended" => Default::default(),
+                _ => app_state.device_manager.control.state(session.agent_id).await,
+            };
+            Json(serde_json::json!({
+                "session": session,
+                "control_holder": control.holder,
+                "has_control": control.holder == Some(session_uuid),
+                "control_request": control.pending,
+                "last_activity": app_state.device_manager.idle_tracker.last_activity(session_uuid).await,
+                "route": app_state.device_manager.relay_manager.get_route(&session_id).await,
+            })).into_response()
+        }
+        None => {
+            (StatusCode::NOT_FOUND, Json(serde_json::json!({
+                "error": format!("Session not found: {}", session_uuid)
+            }))).into_response()
+        }
+    }
+}
+
+/// Latest connection quality of an active session
+pub async fn api_get_session_quality(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_uuid = match Uuid::parse_str(&session_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                "error": "Invalid session ID format"
+            }))).into_response();
+        }
+    };
+
+    if !session_in_tenant(&app_state, tenant, session_uuid).await {
+        return organizations::session_not_found(session_uuid).into_response();
+    }
+
+    match app_state.device_manager.quality_monitor.quality(session_uuid).await {
+        Some(quality) => Json(serde_json::json!({
+            "quality": quality
+        })).into_response(),
+        // Not probed yet, or already ended
+        None => Json(serde_json::json!({
+            "quality": null
+        })).into_response(),
+    }
+}
+
+/// Record a command run from a session window's Commands tab, for the timeline
+pub async fn api_record_session_command(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+    Json(mut command): Json<SessionCommand>,
+) -> Response {
+    let session = match authorized_session(&app_state, &user, &session_id, true).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    if command.stdout.len() + command.stderr.len() > MAX_COMMAND_OUTPUT {
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(serde_json::json!({
+            "error": format!("Command output exceeds {} bytes", MAX_COMMAND_OUTPUT)
+        }))).into_response();
+    }
+
+    command.executed_by = user.email.clone();
+    let status = if app_state.device_manager.record_session_command(&session, user.user_id, command.clone()).await {
+        StatusCode::CREATED
+    } else {
+        // A retried upload
+        StatusCode::OK
+    };
+
+    (status, Json(serde_json::json!({
+        "status": "success",
+        "command": command
+    }))).into_response()
+}
+
+/// Commands run in a session, oldest first
+pub async fn api_get_session_commands(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session = match authorized_session(&app_state, &user, &session_id, false).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    let commands = app_state.device_manager.session_commands(session.id).await;
+    Json(serde_json::json!({
+        "session_id": session.id,
+        "total": commands.len(),
+        "commands": commands
+    })).into_response()
+}
+
+/// Upper bound on events in one upload; the session window sends far fewer
+const MAX_EVENT_BATCH: usize = 500;
+
+/// Timeline events uploaded by a session window
+#[derive(Debug, Deserialize)]
+pub struct SessionEventBatch {
+    pub events: Vec<SessionEvent>,
+}
+
+/// Record timeline events of a session; events seen before are skipped
+pub async fn api_record_session_events(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+    Json(batch): Json<SessionEventBatch>,
+) -> Response {
+    let session = match authorized_session(&app_state, &user, &session_id, true).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    if batch.events.len() > MAX_EVENT_BATCH {
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(serde_json::json!({
+            "error": format!("At most {} events per request", MAX_EVENT_BATCH)
+        }))).into_response();
+    }
+    if let Some(event) = batch.events.iter().find(|event| event.session_id != session.id) {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": format!("Event {} belongs to session {}", event.id, event.session_id)
+        }))).into_response();
+    }
+
+    let mut events = batch.events;
+    let received = events.len();
+    for event in &mut events {
+        if event.actor.trim().is_empty() {
+            event.actor = user.email.clone();
+        }
+    }
+    let added = app_state.device_manager.record_session_events(&session, user.user_id, events).await;
+
+    Json(serde_json::json!({
+        "status": "success",
+        "accepted": added.len(),
+        "duplicates": received - added.len()
+    })).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionEventsQuery {
+    /// Only events at or after this time
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// Timeline events of a session, in timeline order
+pub async fn api_get_session_events(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+    Query(query): Query<SessionEventsQuery>,
+) -> Response {
+    let session = match authorized_session(&app_state, &user, &session_id, false).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    let events = app_state.device_manager.session_events(session.id, query.since).await;
+    Json(serde_json::json!({
+        "session_id": session.id,
+        "total": events.len(),
+        "events": events
+    })).into_response()
+}
+
+/// Look up a session whose agent the user may view, or control when recording
+pub(crate) async fn authorized_session(
+    app_state: &AppState,
+    user: &AuthUser,
+    session_id: &str,
+    needs_control: bool,
+) -> Result<crate::models::Session, Response> {
+    let Ok(session_uuid) = Uuid::parse_str(session_id) else {
+        return Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": "Invalid session ID format"
+        }))).into_response());
+    };
+
+    let tenant = Tenant::of(user).map_err(IntoResponse::into_response)?;
+    let session = app_state.device_manager.get_session(session_uuid).await
+        .filter(|session| tenant.includes(session.organization_id));
+    let Some(session) = session else {
+        return Err((StatusCode::NOT_FOUND, Json(serde_json::json!({
+            "error": format!("Session not found: {}", session_uuid)
+        }))).into_response());
+    };
+
+    authz::check_agent_permission(app_state, user, session.agent_id, needs_control)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Ok(session)
+}
+
 /// Create a new session with a device
 #[derive(Debug, Deserialize)]
 pub struct CreateSessionRequest {
     pub session_type: SessionType,
     pub user_id: Option<String>,
+    /// Codecs, H.264 profiles and resolution the viewer decodes
+    #[serde(default)]
+    pub capabilities: Option<ViewerCapabilities>,
+    /// Technician's region, to pick a relay node near both ends
+    #[serde(default)]
+    pub region: Option<String>,
+    /// `Hybrid` streams frames over the relay's UDP lane when it has one
+    #[serde(default)]
+    pub connection_type: Option<crate::relay::ConnectionType>,
+    /// What the session token allows; everything the session type does when unset
+    #[serde(default)]
+    pub scopes: Option<Vec<SessionScope>>,
+    /// Minutes the session token is valid to attach with
+    #[serde(default)]
+    pub token_ttl_minutes: Option<u32>,
+    /// Bandwidth the session may use, in kbit/s; held to the policy ceiling
+    #[serde(default)]
+    pub bandwidth_kbps: Option<u32>,
+    /// Let the agent zstd-compress frames while the link is the bottleneck
+    #[serde(default)]
+    pub compress_frames: bool,
+    /// Why the session is needed; backstage sessions whose policy requires
+    /// approval are refused without one
+    #[serde(default)]
+    pub justification: Option<String>,
+    /// Ticket in the organization's PSA the session is for
+    #[serde(default)]
+    pub ticket_ref: Option<String>,
 }
 
 pub async fn api_create_session(
     State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
     Path(agent_id): Path<String>,
     Json(request): Json<CreateSessionRequest>,
 ) -> impl IntoResponse {
     match Uuid::parse_str(&agent_id) {
         Ok(agent_uuid) => {
+            // View sessions only need can_view; everything else takes control of the agent
+            let needs_control = !matches!(request.session_type, SessionType::View);
+            if let Err(e) = authz::check_agent_permission(&app_state, &user, agent_uuid, needs_control).await {
+                return e.into_response();
+            }
+            // Guests work within their access grant, under its ID
+            let access_grant = match access_grants::guest_grant(&app_state, &user).await {
+                Ok(grant) => grant,
+                Err(e) => return e.into_response(),
+            };
+            if access_grant.is_some() && request.user_id.is_some() {
+                return (StatusCode::FORBIDDEN, Json(serde_json::json!({
+                    "error": "Guests may only start sessions for themselves"
+                }))).into_response();
+            }
+
             let user_id = match request.user_id {
                 Some(id_str) => match Uuid::parse_str(&id_str) {
                     Ok(uuid) => uuid,
@@ -84,32 +587,154 @@ pub async fn api_create_session(
                         }))
                     ).into_response()
                 },
-                None => Uuid::new_v4(), // Anonymous user
+                None => user.user_id,
+            };
+
+            if let Some(Err(error)) = request.capabilities.as_ref().map(ViewerCapabilities::validate) {
+                return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                    "error": error
+                }))).into_response();
+            }
+            if let Some(Err(error)) = request.bandwidth_kbps.map(bandwidth::validate_cap) {
+                return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                    "error": error
+                }))).into_response();
+            }
+            let ticket_ref = match session_notes::normalize_ticket_ref(request.ticket_ref.as_deref()) {
+                Ok(ticket_ref) => ticket_ref,
+                Err(error) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                    "error": error
+                }))).into_response(),
+            };
+            let grant = session_tokens::grant_scopes(&request.session_type, request.scopes.as_deref())
+                .and_then(|scopes| Ok((scopes, session_tokens::token_ttl(request.token_ttl_minutes)?)));
+            let (scopes, token_ttl) = match grant {
+                Ok(grant) => grant,
+                Err(error) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                    "error": error
+                }))).into_response(),
+            };
+            let (scopes, token_ttl) = match &access_grant {
+                Some(access_grant) => {
+                    match access_grant.session_scopes(&request.session_type, scopes, request.scopes.is_some()) {
+                        Ok(scopes) => (scopes, token_ttl.min(access_grant.expires_at - Utc::now())),
+                        Err(e) => return e.into_response(),
+                    }
+                }
+                None => (scopes, token_ttl),
+            };
+            // Scopes asked for by name must be served; defaults shrink to what the device can do
+            let capabilities = app_state.device_manager.agent_capabilities(agent_uuid).await;
+            let scopes = match &request.scopes {
+                Some(_) => {
+                    let required: Vec<_> = scopes.iter().filter_map(|&scope| AgentCapability::for_scope(scope)).collect();
+                    if let Err(unsupported) = capabilities.check(&required) {
+                        return unsupported.into_response();
+                    }
+                    scopes
+                }
+                None => capabilities.usable_scopes(scopes),
             };
 
+            let session_type = request.session_type.to_string();
             let session_request = SessionRequest {
                 agent_id: agent_uuid,
                 session_type: request.session_type,
                 user_id,
+                capabilities: request.capabilities,
+                bandwidth_kbps: request.bandwidth_kbps,
+                compress_frames: request.compress_frames,
+                ticket_ref,
+            };
+
+            // Backstage sessions on devices whose policy says so wait for
+            // someone other than the requester to approve them
+            let approval_ttl = match session_request.session_type {
+                SessionType::Backstage => app_state.device_manager.backstage_approval_ttl(agent_uuid).await,
+                _ => None,
             };
+            if let Some(ttl) = approval_ttl {
+                let justification = request.justification.as_deref().map(str::trim).unwrap_or_default();
+                if justification.is_empty() || justification.chars().count() > approvals::MAX_JUSTIFICATION_LEN {
+                    return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                        "error": format!(
+                            "Backstage sessions on this device need approval; give a justification of at most {} characters",
+                            approvals::MAX_JUSTIFICATION_LEN
+                        )
+                    }))).into_response();
+                }
+                let held = HeldSession {
+                    request: session_request,
+                    scopes,
+                    token_ttl,
+                    region: request.region,
+                    connection_type: request.connection_type,
+                    access_grant_id: access_grant.as_ref().map(|grant| grant.id),
+                };
+                let approval = app_state.device_manager
+                    .request_backstage(held, user.user_id, &user.email, justification, ttl)
+                    .await;
+                return match approval {
+                    Ok(approval) => {
+                        app_state.device_manager.record_audit(
+                            approvals::audit(&approval, "backstage_approval_requested")
+                                .actor(user.user_id.to_string())
+                                .request(&context),
+                        ).await;
+                        (StatusCode::ACCEPTED, Json(serde_json::json!({
+                            "status": "pending_approval",
+                            "approval": approval,
+                            "message": "Backstage session waits for approval"
+                        }))).into_response()
+                    }
+                    Err(error) => error.into_response(),
+                };
+            }
 
             // For now, create a dummy channel. In a real implementation,
             // this would come from a WebSocket upgrade
-            let (tx, _) = tokio::sync::mpsc::unbounded_channel();
+            let (tx, _) = crate::relay::outbound::channel();
 
+            let ticket_ref = session_request.ticket_ref.clone();
             match app_state.device_manager.create_session(session_request, tx).await {
                 Ok(session_id) => {
+                    let mut details = serde_json::json!({
+                        "session_type": session_type,
+                        "user_id": user_id,
+                        "ticket_ref": ticket_ref,
+                    });
+                    if let Some(access_grant) = &access_grant {
+                        app_state.device_manager.access_grants.attach_session(access_grant.id, session_id).await;
+                        details["access_grant_id"] = serde_json::json!(access_grant.id);
+                    }
+                    app_state.device_manager.record_audit(
+                        AuditLog::new("session", "session_created")
+                            .actor(user.user_id.to_string())
+                            .agent(agent_uuid)
+                            .session(session_id)
+                            .request(&context)
+                            .details(details),
+                    ).await;
+                    let route = app_state.device_manager.route_session(session_id, request.region, request.connection_type).await;
+                    let token = app_state.device_manager.session_tokens
+                        .mint(session_id, user_id, scopes, token_ttl, Utc::now())
+                        .await;
+                    let (token, claims) = match token {
+                        Ok(token) => token,
+                        Err(e) => return e.into_response(),
+                    };
                     Json(serde_json::json!({
                         "status": "success",
                         "session_id": session_id,
+                        "route": route,
+                        "token": token,
+                        "token_expires_at": claims.expires_at(),
+                        "scopes": claims.scopes,
+                        "launch_url": session_tokens::launch_url(session_id, &token),
                         "message": "Session created successfully"
                     })).into_response()
                 },
-                Err(error) => {
-                    (StatusCode::BAD_REQUEST, Json(serde_json::json!({
-                        "error": error
-                    }))).into_response()
-                }
+                Err(error) => error.into_response(),
             }
         },
         Err(_) => {
@@ -123,15 +748,51 @@ pub async fn api_create_session(
 /// End a session
 pub async fn api_end_session(
     State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
     Path(session_id): Path<String>,
 ) -> Response {
     match Uuid::parse_str(&session_id) {
         Ok(session_uuid) => {
+            if !session_in_tenant(&app_state, tenant, session_uuid).await {
+                return organizations::session_not_found(session_uuid).into_response();
+            }
+            match access_grants::guest_grant(&app_state, &user).await {
+                Ok(Some(grant)) if !grant.sessions.contains(&session_uuid) => {
+                    return organizations::session_not_found(session_uuid).into_response();
+                }
+                Ok(_) => {}
+                Err(e) => return e.into_response(),
+            }
+            // The technician's session window asks for the close-out note
+            // when its policy wants one
+            let device_manager = &app_state.device_manager;
+            if let Some(session) = device_manager.get_session(session_uuid).await {
+                if session.ended_at.is_none()
+                    && device_manager.closeout_note_required(session.agent_id).await
+                    && !device_manager.session_notes.has_closeout(session.id).await
+                {
+                    return (StatusCode::PRECONDITION_FAILED, Json(serde_json::json!({
+                        "error": "Write a close-out note before ending this session",
+                        "closeout_required": true
+                    }))).into_response();
+                }
+            }
             match app_state.device_manager.end_session(session_uuid).await {
-                Ok(_) => {
+                Ok(session) => {
+                    app_state.device_manager.record_audit(
+                        AuditLog::new("session", "session_ended")
+                            .actor(user.user_id.to_string())
+                            .agent(session.agent_id)
+                            .session(session.id)
+                            .request(&context)
+                            .details(serde_json::json!({ "ticket_ref": session.ticket_ref })),
+                    ).await;
                     Json(serde_json::json!({
                         "status": "success",
-                        "message": "Session ended successfully"
+                        "message": "Session ended successfully",
+                        "session": session
                     })).into_response()
                 },
                 Err(error) => {
@@ -149,14 +810,67 @@ pub async fn api_end_session(
     }
 }
 
+/// Request body for collecting a directory archive from a device
+#[derive(Debug, Deserialize)]
+pub struct CollectArchiveRequest {
+    pub path: String,
+    #[serde(default)]
+    pub include_globs: Vec<String>,
+    #[serde(default)]
+    pub max_size: u64,
+}
+
+/// Stream a tar.zst of a remote directory straight to the technician's browser
+pub async fn api_collect_archive(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+    Json(request): Json<CollectArchiveRequest>,
+) -> Response {
+    let session_uuid = match authorized_session(&app_state, &user, &session_id, true).await {
+        Ok(session) => session.id,
+        Err(response) => return response,
+    };
+
+    let rx = match app_state.device_manager
+        .start_archive_collection(session_uuid, request.path, request.include_globs, request.max_size)
+        .await
+    {
+        Ok(rx) => rx,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+                "error": error
+            }))).into_response();
+        }
+    };
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    let filename = format!("attachment; filename=\"session-{}.tar.zst\"", session_uuid);
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/zstd")
+        .header(header::CONTENT_DISPOSITION, filename)
+        .body(Body::from_stream(stream))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
 /// Device registration endpoint (for clients)
 pub async fn api_register_device(
     State(app_state): State<AppState>,
     Json(registration): Json<DeviceRegistration>,
 ) -> Response {
+    // Unsigned HTTP registration is only for agents predating enrollment
+    if !app_state.enrollment.allows_unauthenticated_agents() {
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({
+            "error": "Agents must enroll via /relay/enroll and register over the relay WebSocket"
+        }))).into_response();
+    }
+
     // For now, create a dummy channel. In a real implementation,
     // this would come from a WebSocket connection
-    let (tx, _) = tokio::sync::mpsc::unbounded_channel();
+    let (tx, _) = crate::relay::outbound::channel();
 
     match app_state.device_manager.register_device(registration, tx).await {
         Ok(agent_id) => {
@@ -178,20 +892,27 @@ pub async fn api_register_device(
 pub async fn websocket_device_handler(
     ws: WebSocketUpgrade,
     State(app_state): State<AppState>,
+    context: RequestContext,
+    ClientAddress(address): ClientAddress,
     Query(params): Query<HashMap<String, String>>,
 ) -> Response {
-    let agent_id = match params.get("agent_id").cloned() {
-        Some(id) => id,
-        None => {
-            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
-                "error": "Missing agent_id parameter"
-            }))).into_response();
-        }
-    };
+    // The agent identifies itself in its AgentRegister message; a query
+    // parameter, when given, must match it
+    let agent_id = params.get("agent_id").cloned();
     let session_type = params.get("type").cloned().unwrap_or_else(|| "device".to_string());
+    let source_ip = context.source_ip.and_then(|ip| ip.parse().ok());
+    let permit = app_state.device_manager.rate_limiter.acquire_connection(address);
 
     ws.on_upgrade(move |socket| async move {
-        crate::relay::handle_websocket(socket, agent_id, session_type, app_state.device_manager).await;
+        let Some((socket, _permit)) = admit(socket, permit).await else { return };
+        crate::relay::handle_websocket(
+            socket,
+            agent_id,
+            session_type,
+            source_ip,
+            app_state.device_manager,
+            app_state.enrollment,
+        ).await;
     })
 }
 
@@ -199,6 +920,7 @@ pub async fn websocket_device_handler(
 pub async fn websocket_session_handler(
     ws: WebSocketUpgrade,
     State(app_state): State<AppState>,
+    ClientAddress(address): ClientAddress,
     Query(params): Query<HashMap<String, String>>,
 ) -> Response {
     let session_id = match params.get("session_id").cloned() {
@@ -211,7 +933,1513 @@ pub async fn websocket_session_handler(
     };
     let session_type = params.get("type").cloned().unwrap_or_else(|| "viewer".to_string());
 
+    // The token is checked and used up before the upgrade, so a refused
+    // viewer gets an HTTP status instead of a socket that closes at once
+    let Ok(session_uuid) = Uuid::parse_str(&session_id) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": "Invalid session ID format"
+        }))).into_response();
+    };
+    let Some(token) = params.get("token") else {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({
+            "error": "Missing session token"
+        }))).into_response();
+    };
+    let grant = match app_state.device_manager.session_tokens.redeem(token, session_uuid).await {
+        Ok(grant) => grant,
+        Err(e) => {
+            warn!("Refused viewer for session {}: {}", session_id, e);
+            return e.into_response();
+        }
+    };
+    let permit = app_state.device_manager.rate_limiter.acquire_connection(address);
+
     ws.on_upgrade(move |socket| async move {
-        crate::relay::handle_session_websocket(socket, session_id, session_type, app_state.device_manager).await;
+        let Some((socket, _permit)) = admit(socket, permit).await else { return };
+        crate::relay::handle_session_websocket(socket, session_id, session_type, grant, app_state.device_manager).await;
     })
-}
\ No newline at end of file
+}
+
+/// Keep the socket with its connection slot, or close it with the reason it
+/// got none so the client backs off
+pub(crate) async fn admit(
+    mut socket: WebSocket,
+    permit: Result<ConnectionPermit, ConnectionRefused>,
+) -> Option<(WebSocket, ConnectionPermit)> {
+    match permit {
+        Ok(permit) => Some((socket, permit)),
+        Err(refused) => {
+            let _ = socket.send(Message::Close(Some(refused.close_frame()))).await;
+            None
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{access_grants, enrollment::EnrollmentService, invitations},
+        config::AppConfig,
+        device_manager::{DeviceManager, DeviceMetrics},
+        relay::{
+            control::{SessionPolicy, TakeoverTimeout},
+            outbound::{self, OutboundReceiver},
+        },
+    };
+    use axum::{
+        extract::ws::Message,
+        http::{Method, Request},
+        routing::{delete, get, post, put},
+        Router,
+    };
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn state(device_manager: Arc<DeviceManager>) -> AppState {
+        AppState {
+            metrics: device_manager.telemetry.clone(),
+            device_manager,
+            config: AppConfig {
+                host: "127.0.0.1".to_string(),
+                port: 0,
+                dual_stack: true,
+                database_url: None,
+                jwt_secret: "test-secret".to_string(),
+                session_timeout: 3600,
+                max_concurrent_sessions: 10,
+                allow_unauthenticated_agents: false,
+                session_policy: SessionPolicy::default(),
+                idle: Default::default(),
+                liveness: Default::default(),
+                audit_file: Default::default(),
+                relay_broker: Default::default(),
+                relay_udp_port: None,
+                rate_limits: Default::default(),
+                metrics_token: None,
+                relay_url: None,
+                input_coalesce_ms: 0,
+                webhooks: Default::default(),
+                recordings: Default::default(),
+                compression: Default::default(),
+            },
+            db: None,
+            enrollment: Arc::new(EnrollmentService::new("test-secret", false)),
+        }
+    }
+
+    fn app(device_manager: Arc<DeviceManager>) -> Router {
+        Router::new()
+            .route("/api/devices", get(api_get_devices))
+            .route("/api/devices/:id", get(api_get_device))
+            .route("/api/devices/:id/group", put(device_groups::api_set_device_group))
+            .route("/api/devices/:id/tags", put(device_groups::api_set_device_tags))
+            .route("/api/groups", get(device_groups::api_list_groups).post(device_groups::api_create_group))
+            .route("/api/groups/:id", get(device_groups::api_get_group).delete(device_groups::api_delete_group))
+            .route("/api/devices/:id/metrics", get(api_get_device_metrics))
+            .route("/api/devices/:id/sessions", post(api_create_session))
+            .route("/api/sessions", get(api_list_sessions))
+            .route("/api/sessions/:id", get(api_get_session).delete(api_end_session))
+            .route("/api/sessions/:id/quality", get(api_get_session_quality))
+            .route("/api/sessions/:id/commands", get(api_get_session_commands).post(api_record_session_command))
+            .route("/api/sessions/:id/events", get(api_get_session_events).post(api_record_session_events))
+            .route("/api/sessions/:id/fs/archive", post(api_collect_archive))
+            .route("/api/audit", get(crate::audit::api_list_audit))
+            .route("/api/invitations", get(invitations::api_list_invitations).post(invitations::api_create_invitation))
+            .route("/api/invitations/:id", delete(invitations::api_revoke_invitation))
+            .route("/relay/enroll", post(crate::auth::enrollment::api_enroll_device))
+            .route("/api/vpn/peers", get(crate::vpn_integration::api_get_vpn_peers))
+            .route("/api/devices/:id/grants", get(access_grants::api_list_device_access_grants).post(access_grants::api_create_access_grant))
+            .route("/api/grants", get(access_grants::api_list_access_grants))
+            .route("/api/grants/:id", delete(access_grants::api_revoke_access_grant))
+            .route("/api/auth/guest", get(access_grants::api_guest_login))
+            .route("/api/terminal/:client_session_id/create", post(crate::terminal::api_create_terminal_session))
+            .with_state(state(device_manager))
+    }
+
+    async fn call(app: &Router, method: Method, uri: &str) -> (StatusCode, serde_json::Value) {
+        call_with_role(app, "admin", method, uri, None).await
+    }
+
+    async fn call_unauthenticated(app: &Router, method: Method, uri: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    async fn call_as(app: &Router, method: Method, uri: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+        call_with_role(app, "technician", method, uri, body).await
+    }
+
+    async fn call_with_role(
+        app: &Router,
+        role: &str,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        call_in_organization(app, role, None, method, uri, body).await
+    }
+
+    async fn call_in_organization(
+        app: &Router,
+        role: &str,
+        organization_id: Option<Uuid>,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
+        let org_id = organization_id.map(|id| id.to_string());
+        let orgs: Vec<String> = org_id.iter().cloned().collect();
+        let token = crate::auth::jwt::JwtService::new(&secret)
+            .generate_access_token(&Uuid::new_v4(), "tech@example.com", role, org_id.as_deref(), &orgs)
+            .unwrap();
+        call_with_token(app, &token, method, uri, body).await
+    }
+
+    /// Call as the technician `user_id`
+    async fn call_as_user(
+        app: &Router,
+        user_id: Uuid,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        call_as_user_with_role(app, user_id, "technician", method, uri, body).await
+    }
+
+    /// Call as `user_id` in `role`
+    async fn call_as_user_with_role(
+        app: &Router,
+        user_id: Uuid,
+        role: &str,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
+        let token = crate::auth::jwt::JwtService::new(&secret)
+            .generate_access_token(&user_id, &format!("{}@example.com", user_id), role, None, &[])
+            .unwrap();
+        call_with_token(app, &token, method, uri, body).await
+    }
+
+    /// Call as the guest of access grant `grant`, as returned on creation
+    async fn call_as_guest(
+        app: &Router,
+        grant: &serde_json::Value,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
+        let grant_id = Uuid::parse_str(grant["id"].as_str().unwrap()).unwrap();
+        let expires_at = grant["expires_at"].as_str().unwrap().parse().unwrap();
+        let token = crate::auth::jwt::JwtService::new(&secret)
+            .generate_guest_token(&grant_id, "vendor@example.com", None, expires_at)
+            .unwrap();
+        call_with_token(app, &token, method, uri, body).await
+    }
+
+    async fn call_with_token(
+        app: &Router,
+        token: &str,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "application/json");
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        let response = app.clone().oneshot(request.body(body).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    async fn register(device_manager: &DeviceManager, hostname: &str) -> (Uuid, OutboundReceiver) {
+        register_in(device_manager, hostname, None).await
+    }
+
+    async fn register_in(
+        device_manager: &DeviceManager,
+        hostname: &str,
+        organization_id: Option<Uuid>,
+    ) -> (Uuid, OutboundReceiver) {
+        let (tx, rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: hostname.to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id,
+        };
+        (device_manager.register_device(registration, tx).await.unwrap(), rx)
+    }
+
+    async fn start_session(device_manager: &DeviceManager, agent_id: Uuid) -> (Uuid, OutboundReceiver) {
+        let (tx, rx) = outbound::channel();
+        let request = SessionRequest {
+            agent_id,
+            session_type: SessionType::Control,
+            user_id: Uuid::new_v4(),
+            capabilities: None,
+            bandwidth_kbps: None,
+            compress_frames: false,
+            ticket_ref: None,
+        };
+        (device_manager.create_session(request, tx).await.unwrap(), rx)
+    }
+
+    #[tokio::test]
+    async fn test_get_device_reports_online_state() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, _rx) = register(&device_manager, "desk-01").await;
+
+        let (status, body) = call(&app, Method::GET, &format!("/api/devices/{}", agent_id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["online"], true);
+        assert_eq!(body["device"]["hostname"], "desk-01");
+        assert!(body["device"]["last_seen"].is_string());
+
+        device_manager.disconnect_device(agent_id).await;
+        let (status, body) = call(&app, Method::GET, &format!("/api/devices/{}", agent_id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["online"], false);
+        assert_eq!(body["device"]["status"], "offline");
+
+        let (status, _) = call(&app, Method::GET, &format!("/api/devices/{}", Uuid::new_v4())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&app, Method::GET, "/api/devices/not-a-uuid").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_list_devices_by_group_and_tag() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (kiosk, _kiosk_rx) = register(&device_manager, "kiosk-01").await;
+        let (desk, _desk_rx) = register(&device_manager, "desk-01").await;
+        let (server, _server_rx) = register(&device_manager, "build-server").await;
+        device_manager.disconnect_device(server).await;
+
+        let (status, body) = call(&app, Method::GET, "/api/devices").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["devices"][0]["name"], "build-server");
+        assert_eq!(body["devices"][0]["online"], false);
+
+        let (status, group) = call_as(&app, Method::POST, "/api/groups", Some(serde_json::json!({ "name": "Berlin" }))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = call_as(&app, Method::POST, "/api/groups", Some(serde_json::json!({ "name": "berlin" }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let group_id = group["id"].as_str().unwrap();
+        for agent_id in [kiosk, desk] {
+            let uri = format!("/api/devices/{}/group", agent_id);
+            let (status, _) = call_as(&app, Method::PUT, &uri, Some(serde_json::json!({ "group_id": group_id }))).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let uri = format!("/api/devices/{}/tags", kiosk);
+        let (status, body) = call_as(&app, Method::PUT, &uri, Some(serde_json::json!({ "tags": ["POS", "kiosk"] }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["device"]["tags"], serde_json::json!(["kiosk", "pos"]));
+
+        let (_, body) = call(&app, Method::GET, &format!("/api/devices?group={}", group_id)).await;
+        assert_eq!(body["total"], 2);
+        let (_, body) = call(&app, Method::GET, &format!("/api/devices?group={}&tag=pos&q=KIOSK", group_id)).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["devices"][0]["id"], kiosk.to_string());
+        let (_, body) = call(&app, Method::GET, "/api/devices?group=none&online=false").await;
+        assert_eq!(body["devices"][0]["id"], server.to_string());
+        let (_, body) = call(&app, Method::GET, "/api/devices?platform=Linux&online=true&sort=name&order=desc&limit=1").await;
+        assert_eq!((body["total"].as_u64(), body["devices"][0]["name"].as_str()), (Some(2), Some("kiosk-01")));
+        let (_, body) = call(&app, Method::GET, "/api/devices?limit=2&offset=2").await;
+        assert_eq!(body["devices"].as_array().unwrap().len(), 1);
+        let (status, _) = call(&app, Method::GET, "/api/devices?group=berlin").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Assignments survive a reconnect, and deleting the group ungroups its devices
+        device_manager.disconnect_device(kiosk).await;
+        let (tx, _rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "kiosk-01".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.1".to_string(),
+            public_key: None,
+            agent_id: Some(kiosk.to_string()),
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        device_manager.register_device(registration, tx).await.unwrap();
+        let (_, body) = call(&app, Method::GET, &format!("/api/devices?tag=kiosk&group={}&online=true", group_id)).await;
+        assert_eq!(body["total"], 1);
+
+        let (status, _) = call_as(&app, Method::DELETE, &format!("/api/groups/{}", group_id), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, body) = call(&app, Method::GET, "/api/devices?group=none").await;
+        assert_eq!(body["total"], 3);
+        let uri = format!("/api/devices/{}/group", desk);
+        let (status, _) = call_as(&app, Method::PUT, &uri, Some(serde_json::json!({ "group_id": group_id }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_device_metrics_history() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, _rx) = register(&device_manager, "desk-01").await;
+
+        let (_, body) = call(&app, Method::GET, &format!("/api/devices/{}", agent_id)).await;
+        assert!(body["metrics"].is_null());
+
+        for cpu_usage in [10.0, 20.0] {
+            let metrics = DeviceMetrics { cpu_usage, memory_total: 4096, ..Default::default() };
+            device_manager.record_metrics(agent_id, metrics).await.unwrap();
+        }
+
+        let (_, body) = call(&app, Method::GET, &format!("/api/devices/{}", agent_id)).await;
+        assert_eq!(body["metrics"]["cpu_usage"], 20.0);
+
+        let (status, body) = call(&app, Method::GET, &format!("/api/devices/{}/metrics?minutes=5", agent_id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["minutes"], 5);
+        let samples = body["samples"].as_array().unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0]["cpu_usage"], 10.0);
+        assert_eq!(samples[1]["memory_total"], 4096);
+
+        let (status, _) = call(&app, Method::GET, &format!("/api/devices/{}/metrics", Uuid::new_v4())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_filters_and_pages() {
+        let policy = SessionPolicy { max_control_sessions: None, ..Default::default() };
+        let device_manager = Arc::new(DeviceManager::new().with_session_policy(policy));
+        let app = app(device_manager.clone());
+        let (first_agent, _first_rx) = register(&device_manager, "desk-01").await;
+        let (second_agent, _second_rx) = register(&device_manager, "desk-02").await;
+
+        let mut first_sessions = Vec::new();
+        for _ in 0..3 {
+            first_sessions.push(start_session(&device_manager, first_agent).await);
+        }
+        let (_second_session, _viewer_rx) = start_session(&device_manager, second_agent).await;
+        device_manager.end_session(first_sessions[0].0).await.unwrap();
+
+        let (status, body) = call(&app, Method::GET, "/api/sessions").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 4);
+
+        let uri = format!("/api/sessions?agent_id={}", first_agent);
+        let (_, body) = call(&app, Method::GET, &uri).await;
+        assert_eq!(body["total"], 3);
+        assert!(body["sessions"].as_array().unwrap().iter().all(|s| s["agent_id"] == first_agent.to_string()));
+
+        let (_, body) = call(&app, Method::GET, "/api/sessions?status=ended").await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["sessions"][0]["id"], first_sessions[0].0.to_string());
+
+        let (_, body) = call(&app, Method::GET, "/api/sessions?since=2999-01-01T00:00:00Z").await;
+        assert_eq!(body["total"], 0);
+
+        let (_, body) = call(&app, Method::GET, "/api/sessions?limit=3&offset=2").await;
+        assert_eq!(body["total"], 4);
+        assert_eq!(body["sessions"].as_array().unwrap().len(), 2);
+
+        let (status, _) = call(&app, Method::GET, "/api/sessions?agent_id=not-a-uuid").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_organizations_only_see_their_own_devices_and_sessions() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+        let (own_agent, _own_rx) = register_in(&device_manager, "acme-desk", Some(acme)).await;
+        let (other_agent, _other_rx) = register_in(&device_manager, "globex-desk", Some(globex)).await;
+        let (other_session, _viewer_rx) = start_session(&device_manager, other_agent).await;
+        let as_acme = |method: Method, uri: String, body: Option<serde_json::Value>| {
+            let app = app.clone();
+            async move { call_in_organization(&app, "technician", Some(acme), method, &uri, body).await }
+        };
+
+        let (status, body) = as_acme(Method::GET, "/api/devices".to_string(), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["devices"][0]["id"], own_agent.to_string());
+        let (status, body) = as_acme(Method::GET, "/api/sessions".to_string(), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 0);
+
+        // The other organization's records look like they do not exist
+        let (status, _) = as_acme(Method::GET, format!("/api/devices/{}", other_agent), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = as_acme(Method::GET, format!("/api/devices/{}/metrics", other_agent), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = as_acme(Method::GET, format!("/api/sessions/{}", other_session), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = as_acme(Method::DELETE, format!("/api/sessions/{}", other_session), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let request = serde_json::json!({ "session_type": "Control" });
+        let (status, _) = as_acme(Method::POST, format!("/api/devices/{}/sessions", other_agent), Some(request.clone())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = as_acme(Method::POST, format!("/api/devices/{}/sessions", own_agent), Some(request)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Super-admins see every organization
+        let (_, body) = call(&app, Method::GET, "/api/devices").await;
+        assert_eq!(body["total"], 2);
+        let (status, _) = call(&app, Method::GET, &format!("/api/sessions/{}", other_session)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_invitation_codes_enroll_devices_until_used_up() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let acme = Uuid::new_v4();
+        let as_acme = |method: Method, uri: String, body: Option<serde_json::Value>| {
+            let app = app.clone();
+            async move { call_in_organization(&app, "admin", Some(acme), method, &uri, body).await }
+        };
+        let enroll = |code: &str| {
+            serde_json::json!({
+                "code": code,
+                "agent_id": Uuid::new_v4(),
+                "hostname": "till-01",
+                "platform": "windows",
+                "public_key": BASE64.encode([7u8; 32]),
+            })
+        };
+
+        let request = serde_json::json!({ "ttl_minutes": 30, "max_uses": 1 });
+        let (status, invitation) = as_acme(Method::POST, "/api/invitations".to_string(), Some(request)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(invitation["organization_id"], acme.to_string());
+        assert_eq!(invitation["provisioning"]["server_url"], "ws://localhost/relay/ws");
+        assert!(invitation["provisioning"]["enrollment_token"].is_string());
+        assert!(invitation["qr_code"].as_str().unwrap().starts_with("data:image/png;base64,"));
+        let code = invitation["code"].as_str().unwrap();
+        let (_, listed) = as_acme(Method::GET, "/api/invitations".to_string(), None).await;
+        assert_eq!(listed["invitations"][0]["code"], code);
+        let (_, listed) = call_in_organization(&app, "admin", Some(Uuid::new_v4()), Method::GET, "/api/invitations", None).await;
+        assert_eq!(listed["invitations"], serde_json::json!([]));
+
+        let (status, enrolled) = as_acme(Method::POST, "/relay/enroll".to_string(), Some(enroll(code))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(enrolled["organization_id"], acme.to_string());
+        let (status, body) = as_acme(Method::POST, "/relay/enroll".to_string(), Some(enroll(code))).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert!(body["error"].as_str().unwrap().contains("already used"));
+        let (_, listed) = as_acme(Method::GET, "/api/invitations".to_string(), None).await;
+        assert_eq!(listed["invitations"], serde_json::json!([]));
+
+        let (_, invitation) = as_acme(Method::POST, "/api/invitations".to_string(), None).await;
+        let uri = format!("/api/invitations/{}", invitation["id"].as_str().unwrap());
+        let (status, _) = call_in_organization(&app, "admin", Some(Uuid::new_v4()), Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = as_acme(Method::DELETE, uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = as_acme(Method::POST, "/relay/enroll".to_string(), Some(enroll(invitation["code"].as_str().unwrap()))).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body["error"], "invitation code was revoked");
+
+        let (status, _) = as_acme(Method::POST, "/relay/enroll".to_string(), Some(enroll("NOPE2345"))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let request = serde_json::json!({ "max_uses": 0 });
+        let (status, _) = as_acme(Method::POST, "/api/invitations".to_string(), Some(request)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_end_session_tears_down_relay_pair() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, agent_rx) = register(&device_manager, "desk-01").await;
+        let (session_id, viewer_rx) = start_session(&device_manager, agent_id).await;
+
+        // Both ends hear that the only control session holds control
+        for rx in [&viewer_rx, &agent_rx] {
+            assert!(matches!(rx.recv().await, Some(Message::Text(text)) if text.contains("ControlChanged")));
+        }
+
+        device_manager.broadcast_screen_frame(agent_id, vec![0; 100]).await;
+        assert!(matches!(viewer_rx.recv().await, Some(Message::Binary(_))));
+
+        let uri = format!("/api/sessions/{}", session_id);
+        let (status, body) = call(&app, Method::GET, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["session"]["status"], "active");
+        assert_eq!(body["session"]["bytes_transferred"], 100);
+
+        let (status, body) = call_as(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["session"]["status"], "ended");
+        assert!(body["session"]["ended_at"].is_string());
+
+        assert!(matches!(viewer_rx.recv().await, Some(Message::Close(None))));
+        match agent_rx.recv().await {
+            Some(Message::Text(text)) => {
+                let command: serde_json::Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(command["type"], "SessionEnd");
+                assert_eq!(command["session_id"], session_id.to_string());
+            }
+            other => panic!("expected SessionEnd, got {:?}", other),
+        }
+
+        // Ended sessions stay readable but cannot be ended twice
+        let (status, body) = call(&app, Method::GET, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["session"]["status"], "ended");
+        let (status, _) = call_as(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&app, Method::GET, &format!("/api/sessions/{}", Uuid::new_v4())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_session_quality() {
+        use crate::relay::quality::{AgentQualityReport, LinkQuality};
+
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+        let (session_id, _viewer_rx) = start_session(&device_manager, agent_id).await;
+        let uri = format!("/api/sessions/{}/quality", session_id);
+
+        let (status, body) = call(&app, Method::GET, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["quality"].is_null());
+
+        let sequence = device_manager.quality_monitor.next_probe(session_id).await;
+        device_manager.quality_monitor.record_echo(session_id, sequence).await.unwrap();
+        let report = AgentQualityReport {
+            link: LinkQuality { rtt_ms: 180.0, jitter_ms: 5.0, loss_percent: 0.0, samples: 10 },
+            capture_fps: 30,
+            connection_type: "Relayed".to_string(),
+            ..AgentQualityReport::default()
+        };
+        device_manager.quality_monitor.record_report(session_id, report).await;
+
+        let (status, body) = call(&app, Method::GET, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["quality"]["relay"]["samples"], 1);
+        assert_eq!(body["quality"]["agent"]["capture_fps"], 30);
+        assert_eq!(body["quality"]["grade"], "fair");
+
+        // Ended sessions drop their measurements
+        device_manager.end_session(session_id).await.unwrap();
+        assert!(device_manager.quality_monitor.quality(session_id).await.is_none());
+
+        let (status, _) = call(&app, Method::GET, &format!("/api/sessions/{}/quality", Uuid::new_v4())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_session_commands() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+        let (session_id, _viewer_rx) = start_session(&device_manager, agent_id).await;
+        let uri = format!("/api/sessions/{}/commands", session_id);
+
+        let (status, body) = call_as(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 0);
+
+        let command = serde_json::json!({
+            "id": Uuid::new_v4(),
+            "command": "systeminfo",
+            "stdout": "Host Name: DESK-01\n",
+            "exit_code": 0,
+            "execution_time": "2026-01-02T03:04:05Z",
+            "duration_ms": 850,
+            "timeout_seconds": 30,
+            "max_length": 16384,
+            "shell": "cmd",
+            "executed_by": "someone-else@example.com",
+            "truncated": true
+        });
+        let (status, body) = call_as(&app, Method::POST, &uri, Some(command.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["command"]["executed_by"], "tech@example.com");
+
+        // Retried uploads are not recorded twice
+        let (status, _) = call_as(&app, Method::POST, &uri, Some(command.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Commands stay readable once the session ended
+        device_manager.end_session(session_id).await.unwrap();
+        let (status, body) = call_as(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 1);
+        let recorded = &body["commands"][0];
+        assert_eq!(recorded["command"], "systeminfo");
+        assert_eq!(recorded["stderr"], "");
+        assert_eq!(recorded["truncated"], true);
+        assert_eq!(recorded["executed_by"], "tech@example.com");
+
+        let mut oversized = command.clone();
+        oversized["id"] = serde_json::json!(Uuid::new_v4());
+        oversized["stdout"] = serde_json::json!("x".repeat(MAX_COMMAND_OUTPUT + 1));
+        let (status, _) = call_as(&app, Method::POST, &uri, Some(oversized)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status, _) = call_unauthenticated(&app, Method::GET, &uri).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let unknown = format!("/api/sessions/{}/commands", Uuid::new_v4());
+        let (status, _) = call_as(&app, Method::POST, &unknown, Some(command)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call_as(&app, Method::GET, "/api/sessions/nope/commands", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_session_events() {
+        use crate::session_events::SessionEventKind;
+
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+        let (session_id, viewer_rx) = start_session(&device_manager, agent_id).await;
+        let uri = format!("/api/sessions/{}/events", session_id);
+
+        let event = |sequence: u64, time: &str, kind: SessionEventKind| {
+            let mut event = SessionEvent::new(session_id, kind, "", serde_json::json!({ "sequence": sequence }));
+            event.sequence = sequence;
+            event.timestamp = time.parse().unwrap();
+            event
+        };
+        let note = event(1, "2026-01-01T10:00:00Z", SessionEventKind::Note);
+        let tab = event(2, "2026-01-01T10:05:00Z", SessionEventKind::TabSwitch);
+        let command = event(3, "2026-01-01T10:05:00Z", SessionEventKind::Command);
+
+        // Out of order, with a retry of an already delivered event
+        let batch = serde_json::json!({ "events": [command, note] });
+        let (status, body) = call_as(&app, Method::POST, &uri, Some(batch)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["accepted"], 2);
+        let batch = serde_json::json!({ "events": [tab, command] });
+        let (_, body) = call_as(&app, Method::POST, &uri, Some(batch)).await;
+        assert_eq!((body["accepted"].clone(), body["duplicates"].clone()), (1.into(), 1.into()));
+
+        // Viewers get each new event once, as it arrives
+        let mut pushed = Vec::new();
+        while let Ok(Some(Message::Text(text))) = tokio::time::timeout(std::time::Duration::from_millis(50), viewer_rx.recv()).await {
+            let update: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if update["type"] == "SessionEvent" {
+                pushed.push(update["event"]["id"].as_str().unwrap().to_string());
+            }
+        }
+        assert_eq!(pushed, [&note, &command, &tab].map(|event| event.id.to_string()));
+
+        let (status, body) = call_as(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 3);
+        let ids: Vec<_> = body["events"].as_array().unwrap().iter().map(|event| event["id"].clone()).collect();
+        assert_eq!(ids, [&note, &tab, &command].map(|event| serde_json::json!(event.id)));
+        assert_eq!(body["events"][0]["actor"], "tech@example.com");
+
+        let (_, body) = call_as(&app, Method::GET, &format!("{}?since=2026-01-01T10:05:00Z", uri), None).await;
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["events"][0]["kind"], "tab_switch");
+        let (_, body) = call_as(&app, Method::GET, &format!("{}?since=2026-01-01T10:05:01Z", uri), None).await;
+        assert_eq!(body["total"], 0);
+        let (status, _) = call_as(&app, Method::GET, &format!("{}?since=yesterday", uri), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let foreign = SessionEvent::new(Uuid::new_v4(), SessionEventKind::Note, "tech", serde_json::json!({}));
+        let (status, _) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "events": [foreign] }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let too_many: Vec<_> = (0..=MAX_EVENT_BATCH as u64).map(|i| event(i, "2026-01-01T11:00:00Z", SessionEventKind::Other)).collect();
+        let (status, _) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "events": too_many }))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    /// JSON frames queued for a viewer, oldest first
+    async fn drain(viewer_rx: &OutboundReceiver) -> Vec<serde_json::Value> {
+        let mut frames = Vec::new();
+        while let Ok(Some(Message::Text(text))) = tokio::time::timeout(std::time::Duration::from_millis(50), viewer_rx.recv()).await {
+            frames.push(serde_json::from_str(&text).unwrap());
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_control_takeover_between_sessions() {
+        let policy = SessionPolicy { max_control_sessions: Some(2), ..Default::default() };
+        let device_manager = Arc::new(DeviceManager::new().with_session_policy(policy));
+        let app = app(device_manager.clone());
+        let (agent_id, agent_rx) = register(&device_manager, "desk-01").await;
+        let (first, first_rx) = start_session(&device_manager, agent_id).await;
+        let (second, second_rx) = start_session(&device_manager, agent_id).await;
+
+        // Past the limit, and the first session to connect drives the device
+        let request = SessionRequest { agent_id, session_type: SessionType::Control, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        assert!(device_manager.create_session(request, outbound::channel().0).await.is_err());
+        let (_, body) = call(&app, Method::GET, &format!("/api/sessions/{}", second)).await;
+        assert_eq!((body["control_holder"].clone(), body["has_control"].clone()), (serde_json::json!(first), false.into()));
+        assert!(device_manager.forward_input_event(second, vec![1]).await.is_err());
+        drain(&first_rx).await;
+
+        // The holder is prompted and turns the request down
+        device_manager.request_control(second).await.unwrap();
+        let prompts = drain(&first_rx).await;
+        assert_eq!((prompts[0]["type"].as_str(), prompts[0]["session_id"].clone()), (Some("ControlRequested"), serde_json::json!(second)));
+        device_manager.answer_control_request(first, false).await.unwrap();
+        let frames = drain(&second_rx).await;
+        let denied = frames.iter().find(|frame| frame["type"] == "ControlDenied").unwrap();
+        assert_eq!(denied["reason"], "refused");
+
+        // Asked again, the holder leaves without answering and the requester takes over
+        device_manager.request_control(second).await.unwrap();
+        device_manager.end_session(first).await.unwrap();
+        assert_eq!(device_manager.control.holder(agent_id).await, Some(second));
+        let changes: Vec<_> = drain(&second_rx).await.into_iter().filter(|frame| frame["type"] == "ControlChanged").collect();
+        assert_eq!(changes.last().unwrap()["holder"], serde_json::json!(second));
+        let agent_frames = drain(&agent_rx).await;
+        assert_eq!(agent_frames.last().unwrap(), &serde_json::json!({ "type": "ControlChanged", "holder": second }));
+        let (_, body) = call(&app, Method::GET, &format!("/api/sessions/{}", second)).await;
+        assert_eq!(body["has_control"], true);
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_takeover_times_out() {
+        for (takeover_timeout, holder_after) in [(TakeoverTimeout::Deny, 0), (TakeoverTimeout::Grant, 1)] {
+            let policy = SessionPolicy { max_control_sessions: None, takeover_timeout, ..Default::default() };
+            let device_manager = DeviceManager::new().with_session_policy(policy);
+            let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+            let sessions = [
+                start_session(&device_manager, agent_id).await.0,
+                start_session(&device_manager, agent_id).await.0,
+            ];
+
+            device_manager.request_control(sessions[1]).await.unwrap();
+            assert_eq!(device_manager.expire_control_requests(chrono::Utc::now()).await, 0);
+            let later = chrono::Utc::now() + chrono::Duration::seconds(31);
+            assert_eq!(device_manager.expire_control_requests(later).await, 1);
+            assert_eq!(device_manager.control.holder(agent_id).await, Some(sessions[holder_after]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_handlers_are_audited() {
+        let config = crate::audit::AuditFileConfig {
+            dir: std::env::temp_dir().join(format!("ghostlink-api-audit-{}", Uuid::new_v4())),
+            ..Default::default()
+        };
+        let device_manager = Arc::new(
+            DeviceManager::new().with_audit_file(Arc::new(crate::audit::AuditFile::new(&config))),
+        );
+        let app = app(device_manager.clone());
+        let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let (status, body) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "session_type": "View" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let session_id = body["session_id"].clone();
+        let (status, _) = call_as(&app, Method::DELETE, &format!("/api/sessions/{}", session_id.as_str().unwrap()), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Only admins outside an organization read the trail back, newest first
+        let uri = format!("/api/audit?agent={}", agent_id);
+        let (status, _) = call_as(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call_in_organization(&app, "admin", Some(Uuid::new_v4()), Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call_with_role(&app, "admin", Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 2);
+        let entries = body["entries"].as_array().unwrap();
+        let actions: Vec<_> = entries.iter().map(|entry| entry["action"].as_str().unwrap()).collect();
+        assert_eq!(actions, ["session_ended", "session_created"]);
+        assert!(entries.iter().all(|entry| entry["session_id"] == session_id && entry["actor"].is_string()));
+        assert_eq!(entries[1]["details"]["session_type"], "view");
+
+        let (_, body) = call_with_role(&app, "admin", Method::GET, &format!("{}&action=session_created&limit=1", uri), None).await;
+        assert_eq!((body["total"].clone(), body["entries"].as_array().unwrap().len()), (1.into(), 1));
+
+        tokio::fs::remove_dir_all(&config.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_connection_cap_closes_with_back_off_code() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let policy = crate::rate_limit::RateLimitPolicy {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        };
+        let device_manager = Arc::new(DeviceManager::new().with_rate_limits(policy));
+        let router = Router::new()
+            .route("/relay/ws", get(websocket_device_handler))
+            .with_state(state(device_manager.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/relay/ws", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, router.into_make_service_with_connect_info::<std::net::SocketAddr>()).await
+        });
+
+        let (held, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut refused, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        match refused.next().await {
+            Some(Ok(WsMessage::Close(Some(frame)))) => {
+                assert_eq!(u16::from(frame.code), crate::rate_limit::CLOSE_TOO_MANY_CONNECTIONS);
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        let stats = device_manager.get_stats(Tenant::All).await.rate_limiting;
+        assert_eq!((stats.open_connections, stats.refused_connections), (1, 1));
+
+        // Hanging up gives the slot back
+        drop(held);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while device_manager.get_stats(Tenant::All).await.rate_limiting.open_connections > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        let (_admitted, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(device_manager.get_stats(Tenant::All).await.rate_limiting.open_connections, 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_tokens_are_scoped_single_use_and_revocable() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+        let device_manager = Arc::new(DeviceManager::new());
+        let (agent_id, agent_rx) = register(&device_manager, "desk-01").await;
+        let router = Router::new()
+            .route("/api/ws", get(websocket_session_handler))
+            .route("/api/devices/:id/sessions", post(api_create_session))
+            .route("/api/sessions/:id", get(api_get_session).delete(api_end_session))
+            .with_state(state(device_manager.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("ws://{}", listener.local_addr().unwrap());
+        let server = router.clone();
+        tokio::spawn(async move {
+            axum::serve(listener, server.into_make_service_with_connect_info::<std::net::SocketAddr>()).await
+        });
+        let refused_with = |result: Result<_, WsError>| match result {
+            Err(WsError::Http(response)) => response.status().as_u16(),
+            Err(e) => panic!("expected an HTTP refusal, got {}", e),
+            Ok(_) => panic!("expected an HTTP refusal, got a socket"),
+        };
+
+        // Scopes outside the session type are refused; a control session
+        // can be narrowed to viewing
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let request = serde_json::json!({ "session_type": "View", "scopes": ["view", "control"] });
+        let (status, _) = call_as(&router, Method::POST, &uri, Some(request)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let request = serde_json::json!({ "session_type": "Control", "scopes": ["view"], "token_ttl_minutes": 5 });
+        let (status, body) = call_as(&router, Method::POST, &uri, Some(request)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["scopes"], serde_json::json!(["view"]));
+        let launch_url = format!("{}{}", base, body["launch_url"].as_str().unwrap());
+        let (mut viewer, _) = tokio_tungstenite::connect_async(&launch_url).await.unwrap();
+
+        // Input is dropped before it reaches the agent
+        viewer.send(WsMessage::Binary(vec![1, 2, 3])).await.unwrap();
+        let denied = loop {
+            match viewer.next().await {
+                Some(Ok(WsMessage::Text(text))) if text.contains("ScopeDenied") => {
+                    break serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                }
+                Some(Ok(_)) => continue,
+                other => panic!("expected ScopeDenied, got {:?}", other),
+            }
+        };
+        assert_eq!((denied["command"].as_str(), denied["scope"].as_str()), (Some("input"), Some("control")));
+        while let Ok(Some(message)) = tokio::time::timeout(std::time::Duration::from_millis(50), agent_rx.recv()).await {
+            assert!(!matches!(message, Message::Binary(_)), "input reached the agent");
+        }
+
+        // The token attached once; the socket refreshes its own grant
+        assert_eq!(refused_with(tokio_tungstenite::connect_async(&launch_url).await), 401);
+        viewer.send(WsMessage::Text(r#"{"type":"refresh_token"}"#.to_string())).await.unwrap();
+        loop {
+            match viewer.next().await {
+                Some(Ok(WsMessage::Text(text))) if text.contains("SessionTokenRefreshed") => break,
+                Some(Ok(_)) => continue,
+                other => panic!("expected SessionTokenRefreshed, got {:?}", other),
+            }
+        }
+
+        // Ending a session voids tokens nobody has used yet
+        let request = serde_json::json!({ "session_type": "View" });
+        let (_, body) = call_as(&router, Method::POST, &uri, Some(request)).await;
+        let pending = format!("{}{}", base, body["launch_url"].as_str().unwrap());
+        let session_uri = format!("/api/sessions/{}", body["session_id"].as_str().unwrap());
+        let (status, _) = call_as(&router, Method::DELETE, &session_uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(refused_with(tokio_tungstenite::connect_async(&pending).await), 401);
+        let (_, body) = call_as(&router, Method::POST, &uri, Some(serde_json::json!({ "session_type": "View" }))).await;
+        let other_session = format!("{}/api/ws?session_id={}&token=", base, body["session_id"].as_str().unwrap());
+        let token = launch_url.split("token=").nth(1).unwrap();
+        assert_eq!(refused_with(tokio_tungstenite::connect_async(format!("{}{}", other_session, token)).await), 403);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_held_to_agent_capabilities() {
+        use crate::relay::capabilities::{AgentCapabilities, AgentCapability::*};
+
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        // A Wayland desktop without the RemoteDesktop portal, and no PTY
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "kiosk-01".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: AgentCapabilities::reported([ScreenCapture, FileTransfer, MultiMonitor]),
+            organization_id: None,
+        };
+        let agent_id = device_manager.register_device(registration, outbound::channel().0).await.unwrap();
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+
+        let (status, body) = call(&app, Method::GET, "/api/devices").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["devices"][0]["capabilities"], serde_json::json!(["screen_capture", "file_transfer", "multi_monitor"]));
+
+        for session_type in ["Control", "Console", "Adhoc"] {
+            let (status, body) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "session_type": session_type }))).await;
+            assert_eq!(status, StatusCode::CONFLICT, "{}", session_type);
+            assert_eq!(body["reason"], "view_only");
+            assert_eq!(body["error"], "This device supports view-only access");
+            assert_eq!(body["missing_capabilities"], serde_json::json!(["input_control"]));
+        }
+        let (status, body) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "session_type": "Backstage" }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["reason"], "missing_capability");
+        assert_eq!(body["missing_capabilities"], serde_json::json!(["terminal"]));
+        assert!(device_manager.list_sessions(&SessionFilter::default()).await.is_empty());
+
+        // What the device can do still works
+        let (status, body) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "session_type": "View" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["scopes"], serde_json::json!(["view"]));
+        let view_session = body["session_id"].as_str().unwrap().to_string();
+        let (status, body) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "session_type": "FileTransfer" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["scopes"], serde_json::json!(["view", "file"]));
+
+        // A terminal inside a session is refused up front
+        let terminal_uri = format!("/api/terminal/{}/create", view_session);
+        let request = serde_json::json!({ "user_id": "tech", "shell_type": null, "working_directory": null, "environment_vars": null, "elevated": null });
+        let (status, body) = call_as(&app, Method::POST, &terminal_uri, Some(request)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "This device does not support terminals");
+
+        // Agents that never reported a list are not held back
+        let (legacy, _legacy_rx) = register(&device_manager, "desk-01").await;
+        let request = serde_json::json!({ "session_type": "Control", "scopes": ["view", "terminal"] });
+        let (status, _) = call_as(&app, Method::POST, &format!("/api/devices/{}/sessions", legacy), Some(request)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_scrape_reflects_relay_activity() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let mut app_state = state(device_manager.clone());
+        app_state.config.metrics_token = Some("scrape-secret".to_string());
+        let app = Router::new()
+            .route("/api/devices/:id", get(api_get_device))
+            .route("/metrics", get(crate::telemetry::api_metrics))
+            .route_layer(axum::middleware::from_fn_with_state(
+                app_state.metrics.clone(),
+                crate::telemetry::track_requests,
+            ))
+            .with_state(app_state);
+        let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+        let (_session_id, _viewer_rx) = start_session(&device_manager, agent_id).await;
+
+        crate::relay::handle_agent_message(&device_manager, &agent_id.to_string(), Message::Binary(vec![0; 64]))
+            .await
+            .unwrap();
+        let (status, _) = call(&app, Method::GET, &format!("/api/devices/{}", agent_id)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = call_unauthenticated(&app, Method::GET, "/metrics").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let request = Request::builder()
+            .uri("/metrics")
+            .header("Authorization", "Bearer scrape-secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        for series in [
+            "ghostlink_devices_online 1",
+            "ghostlink_sessions_active{session_type=\"control\"} 1",
+            "ghostlink_sessions_active{session_type=\"view\"} 0",
+            "ghostlink_relay_messages_total{peer=\"agent\",payload=\"binary\"} 1",
+            "ghostlink_relay_bytes_total{peer=\"agent\",direction=\"in\"} 64",
+            "ghostlink_relay_frames_total 1",
+            "ghostlink_relay_frame_deliveries_total 1",
+            "ghostlink_websocket_connections 0",
+            "ghostlink_http_request_duration_seconds_count{method=\"GET\",route=\"/api/devices/:id\",status=\"2xx\"} 1",
+            "ghostlink_http_request_duration_seconds_count{method=\"GET\",route=\"/metrics\",status=\"4xx\"} 1",
+        ] {
+            assert!(body.lines().any(|line| line == series), "missing {:?} in:\n{}", series, body);
+        }
+        // Labels are templates and fixed sets, never IDs
+        assert!(!body.contains(&agent_id.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_vpn_peers_include_agents_on_tailnets() {
+        use crate::relay::rendezvous::AgentEndpoints;
+        use crate::vpn_integration::TailnetAddress;
+
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut agents = Vec::new();
+        for (hostname, organization_id, address) in [("till-01", acme, "100.64.0.7"), ("till-02", globex, "100.64.0.8")] {
+            let (agent_id, _rx) = register_in(&device_manager, hostname, Some(organization_id)).await;
+            let tailnet = TailnetAddress { tailnet: "tail1234.ts.net".to_string(), address: address.parse().unwrap() };
+            let endpoints = AgentEndpoints { tailnet: Some(tailnet), ..AgentEndpoints::default() };
+            device_manager.rendezvous.register(agent_id, endpoints, chrono::Utc::now()).await;
+            agents.push(agent_id);
+        }
+
+        let (status, peers) = call(&app, Method::GET, "/api/vpn/peers").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(peers.as_object().unwrap().len(), 2);
+        let till = &peers[agents[0].to_string()];
+        assert_eq!(till["vpn_ip"], "100.64.0.7");
+        assert_eq!(till["connected"], true);
+
+        // An organization only sees its own devices
+        let (_, peers) = call_in_organization(&app, "admin", Some(acme), Method::GET, "/api/vpn/peers", None).await;
+        let peers = peers.as_object().unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[&agents[0].to_string()]["agent_id"], agents[0].to_string());
+    }
+
+    async fn grant_access(app: &Router, agent_id: Uuid, capabilities: &[&str]) -> serde_json::Value {
+        let uri = format!("/api/devices/{}/grants", agent_id);
+        let request = serde_json::json!({ "email": "Vendor@Example.com", "ttl_minutes": 60, "capabilities": capabilities });
+        let (status, grant) = call_with_role(app, "operator", Method::POST, &uri, Some(request)).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", grant);
+        grant
+    }
+
+    #[tokio::test]
+    async fn test_guests_only_see_the_granted_device() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (granted, _granted_rx) = register(&device_manager, "desk-01").await;
+        let (other, _other_rx) = register(&device_manager, "desk-02").await;
+        let grant = grant_access(&app, granted, &["view"]).await;
+        assert_eq!(grant["email"], "vendor@example.com");
+        assert_eq!(grant["scopes"], serde_json::json!(["view"]));
+
+        // The link signs the guest in without an account
+        let (status, body) = call_unauthenticated(&app, Method::GET, grant["link"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["grant"]["id"], grant["id"]);
+        assert!(body["access_token"].is_string());
+        let (status, _) = call_unauthenticated(&app, Method::GET, "/api/auth/guest?token=forged").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = call_as_guest(&app, &grant, Method::GET, "/api/devices", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["devices"][0]["id"], granted.to_string());
+        let (status, _) = call_as_guest(&app, &grant, Method::GET, &format!("/api/devices/{}", granted), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call_as_guest(&app, &grant, Method::GET, &format!("/api/devices/{}", other), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let request = serde_json::json!({ "session_type": "View" });
+        let uri = format!("/api/devices/{}/sessions", other);
+        let (status, _) = call_as_guest(&app, &grant, Method::POST, &uri, Some(request)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = call(&app, Method::GET, &format!("/api/devices/{}/grants", granted)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["grants"][0]["id"], grant["id"]);
+        let (_, body) = call(&app, Method::GET, &format!("/api/devices/{}/grants", other)).await;
+        assert_eq!(body["grants"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_guest_sessions_stay_within_the_grant() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let viewing = grant_access(&app, agent_id, &["view"]).await;
+        let files = grant_access(&app, agent_id, &["files"]).await;
+
+        let (status, _) = call_as_guest(&app, &viewing, Method::POST, &uri, Some(serde_json::json!({ "session_type": "Control" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call_as_guest(&app, &viewing, Method::POST, &uri, Some(serde_json::json!({ "session_type": "View" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["scopes"], serde_json::json!(["view"]));
+        let session = device_manager.get_session(Uuid::parse_str(body["session_id"].as_str().unwrap()).unwrap()).await.unwrap();
+        assert_eq!(session.user_id.to_string(), viewing["id"].as_str().unwrap());
+        let someone = serde_json::json!({ "session_type": "View", "user_id": Uuid::new_v4() });
+        let (status, _) = call_as_guest(&app, &viewing, Method::POST, &uri, Some(someone)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Viewing comes with every grant; files do not bring control along
+        assert_eq!(files["scopes"], serde_json::json!(["view", "file"]));
+        let (status, body) = call_as_guest(&app, &files, Method::POST, &uri, Some(serde_json::json!({ "session_type": "FileTransfer" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body["scopes"].as_array().unwrap().contains(&serde_json::json!("control")));
+        let (status, _) = call_as_guest(&app, &files, Method::POST, &uri, Some(serde_json::json!({ "session_type": "Console" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Guests end their own sessions only
+        let (_, body) = call_as(&app, Method::POST, &uri, Some(serde_json::json!({ "session_type": "View" }))).await;
+        let uri = format!("/api/sessions/{}", body["session_id"].as_str().unwrap());
+        let (status, _) = call_as_guest(&app, &viewing, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_view_only_guests_cannot_take_files() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, agent_rx) = register(&device_manager, "desk-01").await;
+        let viewing = grant_access(&app, agent_id, &["view"]).await;
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let (_, body) = call_as_guest(&app, &viewing, Method::POST, &uri, Some(serde_json::json!({ "session_type": "View" }))).await;
+        let session_id = body["session_id"].as_str().unwrap().to_string();
+        drain(&agent_rx).await;
+
+        let uri = format!("/api/sessions/{}/fs/archive", session_id);
+        let request = serde_json::json!({ "path": "/home" });
+        let (status, _) = call_as_guest(&app, &viewing, Method::POST, &uri, Some(request)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(drain(&agent_rx).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_revoking_a_grant_ends_its_sessions() {
+        let config = crate::audit::AuditFileConfig {
+            dir: std::env::temp_dir().join(format!("ghostlink-api-grants-{}", Uuid::new_v4())),
+            ..Default::default()
+        };
+        let device_manager = Arc::new(
+            DeviceManager::new().with_audit_file(Arc::new(crate::audit::AuditFile::new(&config))),
+        );
+        let app = app(device_manager.clone());
+        let (agent_id, agent_rx) = register(&device_manager, "desk-01").await;
+        let grant = grant_access(&app, agent_id, &["view", "control"]).await;
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let (status, body) = call_as_guest(&app, &grant, Method::POST, &uri, Some(serde_json::json!({ "session_type": "Console" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let session_id = body["session_id"].clone();
+        drain(&agent_rx).await;
+
+        let revoke = format!("/api/grants/{}", grant["id"].as_str().unwrap());
+        let (status, body) = call_with_role(&app, "operator", Method::DELETE, &revoke, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["revoked_at"].is_string());
+        let frames = drain(&agent_rx).await;
+        assert!(frames.iter().any(|frame| frame["type"] == "SessionEnd" && frame["session_id"] == session_id));
+
+        let (status, _) = call_as_guest(&app, &grant, Method::GET, "/api/devices", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_unauthenticated(&app, Method::GET, grant["link"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (_, body) = call(&app, Method::GET, "/api/grants").await;
+        assert_eq!(body["grants"], serde_json::json!([]));
+
+        // The guest's activity is on record under the grant
+        let (_, body) = call(&app, Method::GET, &format!("/api/audit?agent={}", agent_id)).await;
+        let entries = body["entries"].as_array().unwrap();
+        let actions: Vec<_> = entries.iter().map(|entry| entry["action"].as_str().unwrap()).collect();
+        assert_eq!(actions, ["access_grant_revoked", "grant_revoked", "session_created", "access_grant_created"]);
+        assert_eq!(entries[1]["actor"], grant["id"]);
+        assert_eq!(entries[2]["actor"], grant["id"]);
+        assert_eq!(entries[2]["details"]["access_grant_id"], grant["id"]);
+
+        tokio::fs::remove_dir_all(&config.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_grants_end_their_sessions() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let app = app(device_manager.clone());
+        let (agent_id, _agent_rx) = register(&device_manager, "desk-01").await;
+        let grant = grant_access(&app, agent_id, &["view"]).await;
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let (_, body) = call_as_guest(&app, &grant, Method::POST, &uri, Some(serde_json::json!({ "session_type": "View" }))).await;
+        let session_id = Uuid::parse_str(body["session_id"].as_str().unwrap()).unwrap();
+        let token_expires_at: DateTime<Utc> = body["token_expires_at"].as_str().unwrap().parse().unwrap();
+        let grant_expires_at: DateTime<Utc> = grant["expires_at"].as_str().unwrap().parse().unwrap();
+        assert!(token_expires_at <= grant_expires_at);
+
+        assert_eq!(device_manager.expire_access_grants(Utc::now()).await, 0);
+        let later = Utc::now() + chrono::Duration::minutes(61);
+        assert_eq!(device_manager.expire_access_grants(later).await, 1);
+        assert_eq!(device_manager.get_session(session_id).await.unwrap().status, "ended");
+        assert_eq!(device_manager.expire_access_grants(later).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_session_transfer_hands_over_without_reconnecting_the_agent() {
+        use futures_util::{SinkExt, Stream, StreamExt};
+        use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+        /// Next JSON frame of `kind` on a viewer's socket, skipping the rest
+        async fn expect<S>(socket: &mut S, kind: &str) -> serde_json::Value
+        where
+            S: Stream<Item = Result<WsMessage, WsError>> + Unpin,
+        {
+            loop {
+                match socket.next().await {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        if frame["type"] == kind {
+                            return frame;
+                        }
+                    }
+                    Some(Ok(_)) => continue,
+                    other => panic!("expected {}, got {:?}", kind, other),
+                }
+            }
+        }
+
+        let device_manager = Arc::new(DeviceManager::new());
+        let (agent_id, agent_rx) = register(&device_manager, "desk-01").await;
+        let router = Router::new()
+            .route("/api/ws", get(websocket_session_handler))
+            .route("/api/devices/:id/sessions", post(api_create_session))
+            .route("/api/sessions/:id", get(api_get_session))
+            .route("/api/sessions/:id/transfer", post(crate::session_transfers::api_offer_transfer))
+            .route("/api/transfers", get(crate::session_transfers::api_list_transfers))
+            .route("/api/transfers/:id/accept", post(crate::session_transfers::api_accept_transfer))
+            .with_state(state(device_manager.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("ws://{}", listener.local_addr().unwrap());
+        let server = router.clone();
+        tokio::spawn(async move {
+            axum::serve(listener, server.into_make_service_with_connect_info::<std::net::SocketAddr>()).await
+        });
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let (_, body) = call_as_user(&router, first, Method::POST, &uri, Some(serde_json::json!({ "session_type": "Control" }))).await;
+        let session_id = body["session_id"].as_str().unwrap().to_string();
+        let (mut original, _) = tokio_tungstenite::connect_async(format!("{}{}", base, body["launch_url"].as_str().unwrap())).await.unwrap();
+        expect(&mut original, "ControlChanged").await;
+
+        // Only the technician running the session offers it, and only the
+        // colleague it is offered to accepts
+        let offer = serde_json::json!({ "to_user": second, "shadow": true });
+        let transfer_uri = format!("/api/sessions/{}/transfer", session_id);
+        let (status, _) = call_as_user(&router, second, Method::POST, &transfer_uri, Some(offer.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, transfer) = call_as_user(&router, first, Method::POST, &transfer_uri, Some(offer)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(expect(&mut original, "SessionTransfer").await["transfer"]["state"], "offered");
+        let (_, body) = call_as_user(&router, second, Method::GET, "/api/transfers", None).await;
+        assert_eq!(body["transfers"][0]["id"], transfer["id"]);
+        let accept_uri = format!("/api/transfers/{}/accept", transfer["id"].as_str().unwrap());
+        let (status, _) = call_as_user(&router, Uuid::new_v4(), Method::POST, &accept_uri, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call_as_user(&router, second, Method::POST, &accept_uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["session_id"], session_id.as_str());
+
+        // The colleague watches the same stream but cannot drive the device
+        let (mut shadow, _) = tokio_tungstenite::connect_async(format!("{}{}", base, body["launch_url"].as_str().unwrap())).await.unwrap();
+        assert_eq!(expect(&mut shadow, "ViewerRole").await["role"], "observer");
+        assert_eq!(expect(&mut original, "SessionTransfer").await["transfer"]["state"], "accepted");
+        assert_eq!(expect(&mut original, "SessionTransfer").await["transfer"]["state"], "shadowing");
+        drain(&agent_rx).await;
+        device_manager.broadcast_screen_frame(agent_id, vec![7, 7, 7]).await;
+        loop {
+            match shadow.next().await {
+                Some(Ok(WsMessage::Binary(data))) => break assert_eq!(data, vec![7, 7, 7]),
+                Some(Ok(_)) => continue,
+                other => panic!("expected a frame, got {:?}", other),
+            }
+        }
+        shadow.send(WsMessage::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(expect(&mut shadow, "ScopeDenied").await["command"], "input");
+
+        // Taking over swaps the viewers; the agent keeps its session and
+        // only learns who it is talking to now
+        shadow.send(WsMessage::Text(r#"{"type":"complete_transfer"}"#.to_string())).await.unwrap();
+        assert_eq!(expect(&mut shadow, "ViewerRole").await["role"], "operator");
+        assert_eq!(expect(&mut shadow, "SessionTransfer").await["transfer"]["state"], "completed");
+        loop {
+            match original.next().await {
+                Some(Ok(WsMessage::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => panic!("expected the original viewer to close, got {}", e),
+            }
+        }
+        let frames = drain(&agent_rx).await;
+        let changed = frames.iter().find(|frame| frame["type"] == "SessionOperatorChanged").unwrap();
+        assert_eq!((changed["session_id"].as_str(), changed["requester"].clone()), (Some(session_id.as_str()), serde_json::json!(format!("{}@example.com", second))));
+        assert!(!frames.iter().any(|frame| frame["type"] == "SessionEnd"));
+        shadow.send(WsMessage::Binary(vec![1, 2, 3])).await.unwrap();
+        let input = tokio::time::timeout(std::time::Duration::from_secs(1), agent_rx.recv()).await.unwrap();
+        assert!(matches!(input, Some(Message::Binary(_))));
+        let (_, body) = call_as_user(&router, second, Method::GET, &format!("/api/sessions/{}", session_id), None).await;
+        assert_eq!(body["session"]["user_id"], serde_json::json!(second));
+        assert_eq!(body["has_control"], true);
+    }
+
+    #[tokio::test]
+    async fn test_backstage_sessions_wait_for_a_second_persons_approval() {
+        let config = crate::audit::AuditFileConfig {
+            dir: std::env::temp_dir().join(format!("ghostlink-api-approvals-{}", Uuid::new_v4())),
+            ..Default::default()
+        };
+        let device_manager = Arc::new(
+            DeviceManager::new().with_audit_file(Arc::new(crate::audit::AuditFile::new(&config))),
+        );
+        let (agent_id, agent_rx) = register(&device_manager, "db-01").await;
+        // The policy of devices in no organization
+        let document = crate::policies::PolicyDocument { backstage_requires_approval: Some(true), ..Default::default() };
+        device_manager.create_policy(Tenant::All, crate::policies::PolicyScope::Organization, None, document).await.unwrap();
+        let router = Router::new()
+            .route("/api/devices/:id/sessions", post(api_create_session))
+            .route("/api/approvals", get(approvals::api_list_approvals))
+            .route("/api/approvals/:id", get(approvals::api_get_approval))
+            .route("/api/approvals/:id/approve", post(approvals::api_approve))
+            .route("/api/approvals/:id/deny", post(approvals::api_deny))
+            .with_state(state(device_manager.clone()));
+        let (technician, admin) = (Uuid::new_v4(), Uuid::new_v4());
+        drain(&agent_rx).await;
+
+        // Console sessions are not held, and backstage ones need a reason
+        let uri = format!("/api/devices/{}/sessions", agent_id);
+        let (status, _) = call_as_user(&router, technician, Method::POST, &uri, Some(serde_json::json!({ "session_type": "Console" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let console_sessions = device_manager.get_device_sessions(agent_id).await.len();
+        let (status, _) = call_as_user(&router, technician, Method::POST, &uri, Some(serde_json::json!({ "session_type": "Backstage" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let request = serde_json::json!({ "session_type": "Backstage", "justification": "Ticket 4411: disk full" });
+        let (status, body) = call_as_user(&router, technician, Method::POST, &uri, Some(request.clone())).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["status"], "pending_approval");
+        assert!(body.get("token").is_none());
+        let approval_id = body["approval"]["id"].as_str().unwrap().to_string();
+        assert_eq!(device_manager.get_device_sessions(agent_id).await.len(), console_sessions);
+        assert!(drain(&agent_rx).await.is_empty());
+
+        // The requester cannot decide it, even as an admin, nor can a
+        // technician who is no approver
+        let approve_uri = format!("/api/approvals/{}/approve", approval_id);
+        let (status, _) = call_as_user(&router, Uuid::new_v4(), Method::POST, &approve_uri, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call_as_user_with_role(&router, admin, "admin", Method::POST, &uri, Some(request)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let own_uri = format!("/api/approvals/{}/approve", body["approval"]["id"].as_str().unwrap());
+        let (status, _) = call_as_user_with_role(&router, admin, "admin", Method::POST, &own_uri, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (_, body) = call_as_user_with_role(&router, admin, "admin", Method::GET, "/api/approvals", None).await;
+        assert_eq!(body["approvals"].as_array().unwrap().len(), 2);
+        let (_, body) = call_as_user(&router, technician, Method::GET, "/api/approvals", None).await;
+        assert_eq!(body["approvals"].as_array().unwrap().len(), 1);
+
+        // Approving starts the session, and only the requester gets its token
+        let (status, body) = call_as_user_with_role(&router, admin, "admin", Method::POST, &approve_uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["approval"]["state"], "approved");
+        let session_id = body["approval"]["session_id"].as_str().unwrap().to_string();
+        let session = device_manager.get_active_session(Uuid::parse_str(&session_id).unwrap()).await.unwrap();
+        assert_eq!((session.session_type.as_str(), session.user_id), ("backstage", technician));
+        let (status, _) = call_as_user_with_role(&router, admin, "admin", Method::POST, &approve_uri, None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let status_uri = format!("/api/approvals/{}", approval_id);
+        let (_, body) = call_as_user(&router, technician, Method::GET, &status_uri, None).await;
+        assert!(body["launch"]["launch_url"].as_str().unwrap().contains(&session_id));
+        let (_, body) = call_as_user_with_role(&router, admin, "admin", Method::GET, &status_uri, None).await;
+        assert!(body.get("launch").is_none());
+        let (status, _) = call_as_user(&router, Uuid::new_v4(), Method::GET, &status_uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let filter = crate::audit::AuditFilter { action: Some("backstage_approved".to_string()), ..Default::default() };
+        let (entries, _) = device_manager.query_audit(&filter, 10, 0).await.unwrap();
+        assert_eq!(entries[0].actor.as_deref(), Some(admin.to_string().as_str()));
+        assert_eq!(entries[0].details["requested_by"], serde_json::json!(technician));
+        assert_eq!(entries[0].details["justification"], "Ticket 4411: disk full");
+
+        tokio::fs::remove_dir_all(&config.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closeout_note_before_ending_a_session() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let (agent_id, _agent_rx) = register(&device_manager, "till-03").await;
+        let document = crate::policies::PolicyDocument { closeout_note_required: Some(true), ..Default::default() };
+        device_manager.create_policy(Tenant::All, crate::policies::PolicyScope::Organization, None, document).await.unwrap();
+        let router = Router::new()
+            .route("/api/devices/:id/sessions", post(api_create_session))
+            .route("/api/sessions/:id", delete(api_end_session))
+            .route("/api/sessions/:id/notes", get(session_notes::api_list_session_notes))
+            .route("/api/sessions/:id/notes", post(session_notes::api_add_session_note))
+            .route("/api/sessions/:id/ticket", put(session_notes::api_set_ticket_ref))
+            .with_state(state(device_manager.clone()));
+        let technician = Uuid::new_v4();
+
+        let request = serde_json::json!({ "session_type": "Control", "ticket_ref": " INC-4711 " });
+        let (status, body) = call_as_user(&router, technician, Method::POST, &format!("/api/devices/{}/sessions", agent_id), Some(request)).await;
+        assert_eq!(status, StatusCode::OK);
+        let session_uri = format!("/api/sessions/{}", body["session_id"].as_str().unwrap());
+        let (status, body) = call_as_user(&router, technician, Method::GET, &format!("{}/notes", session_uri), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ticket_ref"], "INC-4711");
+
+        // A working note doesn't close the session out
+        let note = serde_json::json!({ "body": "Reinstalled the receipt printer driver" });
+        let (status, _) = call_as_user(&router, technician, Method::POST, &format!("{}/notes", session_uri), Some(note)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call_as_user(&router, technician, Method::DELETE, &session_uri, None).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(body["closeout_required"], true);
+
+        let ticket = serde_json::json!({ "ticket_ref": "INC-4712" });
+        let (status, body) = call_as_user(&router, technician, Method::PUT, &format!("{}/ticket", session_uri), Some(ticket)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ticket_ref"], "INC-4712");
+
+        let closeout = serde_json::json!({ "id": Uuid::new_v4(), "body": "Printing works again", "closeout": true });
+        let (status, _) = call_as_user(&router, technician, Method::POST, &format!("{}/notes", session_uri), Some(closeout.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        // Sent again after a lost response, it is the same note
+        let (status, _) = call_as_user(&router, technician, Method::POST, &format!("{}/notes", session_uri), Some(closeout)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = call_as_user(&router, technician, Method::DELETE, &session_uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["session"]["ticket_ref"], "INC-4712");
+        let (_, body) = call_as_user(&router, technician, Method::GET, &format!("{}/notes", session_uri), None).await;
+        assert_eq!(body["notes"].as_array().unwrap().len(), 2);
+    }
+}
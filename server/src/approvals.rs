@@ -0,0 +1,536 @@
+//! Second-person approval of backstage sessions
+//!
+//! A device whose policy sets `backstage_requires_approval` does not start
+//! backstage sessions on request. `POST /api/devices/:id/sessions` has to
+//! carry a `justification` and answers 202 with a pending approval instead;
+//! nothing is created, routed or sent to the agent yet. Approvers — admins,
+//! and users an OIDC claim mapping marks as approvers — hear about it on the
+//! events WebSocket and through the `backstage.approval.requested` webhook,
+//! find it in `GET /api/approvals` and approve or deny it. Nobody decides a
+//! request of their own. Approving starts the session as it was asked for,
+//! and the requester picks up its token from `GET /api/approvals/:id`. A
+//! request nobody decides expires. Other session types are not held.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::{authz, jwt::{error, AuthUser}, session_tokens::SessionScope};
+use crate::device_manager::SessionRequest;
+use crate::models::AuditLog;
+use crate::organizations::Tenant;
+use crate::relay::ConnectionType;
+use crate::AppState;
+
+/// Seconds a request waits for a decision when policy names no lifetime
+pub const DEFAULT_TTL_SECS: u64 = 15 * 60;
+
+/// Longest justification a request may give, in characters
+pub const MAX_JUSTIFICATION_LEN: usize = 1000;
+
+/// How long a decided or expired request is kept, so the requester is told
+/// what became of it instead of that it never existed
+const RETENTION_MINUTES: i64 = 60;
+
+/// How often requests are checked for their expiry
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalState {
+    /// Waiting for an approver
+    Pending,
+    /// Approved, and the session started
+    Approved,
+    Denied,
+    Expired,
+    /// Approved, but the session could not be started, e.g. because the
+    /// device went offline in the meantime
+    Failed,
+}
+
+/// The session a request holds back, started as it was asked for once
+/// someone approves it
+#[derive(Debug, Clone)]
+pub struct HeldSession {
+    pub request: SessionRequest,
+    pub scopes: Vec<SessionScope>,
+    pub token_ttl: Duration,
+    pub region: Option<String>,
+    pub connection_type: Option<ConnectionType>,
+    /// Access grant a guest asked under
+    pub access_grant_id: Option<Uuid>,
+}
+
+/// How the requester attaches to the approved session
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Launch {
+    pub token: String,
+    pub token_expires_at: DateTime<Utc>,
+    pub scopes: Vec<SessionScope>,
+    pub launch_url: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Approval {
+    pub id: Uuid,
+    pub agent_id: Uuid,
+    pub organization_id: Option<Uuid>,
+    /// Technician who asked for the session
+    pub requested_by: Uuid,
+    /// How approvers see the requester, from their account
+    pub requester_name: String,
+    pub justification: String,
+    pub state: ApprovalState,
+    /// Approver who approved or denied it
+    pub decided_by: Option<Uuid>,
+    /// Why it was denied, or why the approved session could not start
+    pub reason: Option<String>,
+    /// Session started on approval
+    pub session_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub held: HeldSession,
+    /// Only ever shown to the requester
+    #[serde(skip)]
+    pub launch: Option<Launch>,
+}
+
+impl Approval {
+    /// Whether `user_id` asked for it, or would run the session it holds
+    pub fn is_requester(&self, user_id: Uuid) -> bool {
+        user_id == self.requested_by || user_id == self.held.request.user_id
+    }
+
+    fn check_open(&self, now: DateTime<Utc>) -> Result<(), ApprovalError> {
+        match self.state {
+            ApprovalState::Pending if now >= self.expires_at => Err(ApprovalError::Expired),
+            ApprovalState::Pending => Ok(()),
+            ApprovalState::Expired => Err(ApprovalError::Expired),
+            _ => Err(ApprovalError::Closed),
+        }
+    }
+
+    /// `approver` approves the request; the session is started afterwards
+    pub fn approve(&mut self, approver: Uuid, now: DateTime<Utc>) -> Result<(), ApprovalError> {
+        self.check_open(now)?;
+        if self.is_requester(approver) {
+            return Err(ApprovalError::SelfApproval);
+        }
+        self.state = ApprovalState::Approved;
+        self.decided_by = Some(approver);
+        self.updated_at = now;
+        Ok(())
+    }
+
+    /// `user_id` denies the request, or withdraws it as its requester
+    pub fn deny(&mut self, user_id: Uuid, reason: Option<String>, now: DateTime<Utc>) -> Result<(), ApprovalError> {
+        self.check_open(now)?;
+        self.state = ApprovalState::Denied;
+        self.decided_by = Some(user_id);
+        self.reason = reason;
+        self.updated_at = now;
+        Ok(())
+    }
+
+    /// Expire a request past its deadline that nobody decided
+    pub fn expire(&mut self, now: DateTime<Utc>) -> bool {
+        if self.state != ApprovalState::Pending || now < self.expires_at {
+            return false;
+        }
+        self.state = ApprovalState::Expired;
+        self.updated_at = now;
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApprovalError {
+    Unknown,
+    /// The caller may not decide backstage requests
+    NotApprover,
+    /// The caller asked for the session, or would run it
+    SelfApproval,
+    Expired,
+    /// Already approved, denied or failed
+    Closed,
+}
+
+impl std::fmt::Display for ApprovalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApprovalError::Unknown => write!(f, "unknown approval request"),
+            ApprovalError::NotApprover => write!(f, "only approvers may decide backstage requests"),
+            ApprovalError::SelfApproval => write!(f, "a backstage request must be approved by someone other than its requester"),
+            ApprovalError::Expired => write!(f, "approval request expired"),
+            ApprovalError::Closed => write!(f, "approval request was already decided"),
+        }
+    }
+}
+
+impl IntoResponse for ApprovalError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ApprovalError::Unknown => StatusCode::NOT_FOUND,
+            ApprovalError::NotApprover | ApprovalError::SelfApproval => StatusCode::FORBIDDEN,
+            ApprovalError::Closed => StatusCode::CONFLICT,
+            ApprovalError::Expired => StatusCode::GONE,
+        };
+        error(status, self.to_string())
+    }
+}
+
+/// Pending backstage requests, and those decided recently
+#[derive(Debug, Default)]
+pub struct ApprovalTracker {
+    approvals: RwLock<HashMap<Uuid, Approval>>,
+}
+
+impl ApprovalTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold `held` back until someone approves it
+    #[allow(clippy::too_many_arguments)]
+    pub async fn request(
+        &self,
+        held: HeldSession,
+        organization_id: Option<Uuid>,
+        requested_by: Uuid,
+        requester_name: &str,
+        justification: &str,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Approval {
+        let approval = Approval {
+            id: Uuid::new_v4(),
+            agent_id: held.request.agent_id,
+            organization_id,
+            requested_by,
+            requester_name: requester_name.to_string(),
+            justification: justification.to_string(),
+            state: ApprovalState::Pending,
+            decided_by: None,
+            reason: None,
+            session_id: None,
+            created_at: now,
+            expires_at: now + ttl,
+            updated_at: now,
+            held,
+            launch: None,
+        };
+        self.approvals.write().await.insert(approval.id, approval.clone());
+        approval
+    }
+
+    pub async fn get(&self, approval_id: Uuid) -> Option<Approval> {
+        self.approvals.read().await.get(&approval_id).cloned()
+    }
+
+    /// Requests `user_id` may see in `tenant`, oldest first: their own,
+    /// and everyone's pending ones if they are an approver
+    pub async fn visible_to(&self, tenant: Tenant, user_id: Uuid, approver: bool) -> Vec<Approval> {
+        let mut approvals: Vec<Approval> = self.approvals.read().await.values()
+            .filter(|approval| tenant.includes(approval.organization_id))
+            .filter(|approval| approval.is_requester(user_id) || (approver && approval.state == ApprovalState::Pending))
+            .cloned()
+            .collect();
+        approvals.sort_by_key(|approval| approval.created_at);
+        approvals
+    }
+
+    /// Pending requests for devices `include` accepts
+    pub async fn pending(&self, include: impl Fn(&Approval) -> bool) -> Vec<Approval> {
+        let mut approvals: Vec<Approval> = self.approvals.read().await.values()
+            .filter(|approval| approval.state == ApprovalState::Pending && include(approval))
+            .cloned()
+            .collect();
+        approvals.sort_by_key(|approval| approval.created_at);
+        approvals
+    }
+
+    /// Run `change` on the request `approval_id`, returning it changed
+    async fn update<F>(&self, approval_id: Uuid, change: F) -> Result<Approval, ApprovalError>
+    where
+        F: FnOnce(&mut Approval) -> Result<(), ApprovalError>,
+    {
+        let mut approvals = self.approvals.write().await;
+        let approval = approvals.get_mut(&approval_id).ok_or(ApprovalError::Unknown)?;
+        change(approval)?;
+        Ok(approval.clone())
+    }
+
+    pub async fn approve(&self, approval_id: Uuid, approver: Uuid, now: DateTime<Utc>) -> Result<Approval, ApprovalError> {
+        self.update(approval_id, |approval| approval.approve(approver, now)).await
+    }
+
+    pub async fn deny(&self, approval_id: Uuid, user_id: Uuid, reason: Option<String>, now: DateTime<Utc>) -> Result<Approval, ApprovalError> {
+        self.update(approval_id, |approval| approval.deny(user_id, reason, now)).await
+    }
+
+    /// The approved request's session started as `session_id`
+    pub async fn started(&self, approval_id: Uuid, session_id: Uuid, launch: Option<Launch>) -> Result<Approval, ApprovalError> {
+        self.update(approval_id, |approval| {
+            approval.session_id = Some(session_id);
+            approval.launch = launch;
+            Ok(())
+        }).await
+    }
+
+    /// The approved request's session could not be started
+    pub async fn failed(&self, approval_id: Uuid, reason: String, now: DateTime<Utc>) -> Result<Approval, ApprovalError> {
+        self.update(approval_id, |approval| {
+            approval.state = ApprovalState::Failed;
+            approval.reason = Some(reason);
+            approval.updated_at = now;
+            Ok(())
+        }).await
+    }
+
+    /// Expire requests past their deadline, returning them, and drop those
+    /// decided longer ago than the retention
+    pub async fn expire(&self, now: DateTime<Utc>) -> Vec<Approval> {
+        let mut approvals = self.approvals.write().await;
+        let expired = approvals.values_mut()
+            .filter_map(|approval| approval.expire(now).then(|| approval.clone()))
+            .collect();
+        approvals.retain(|_, approval| {
+            approval.state == ApprovalState::Pending
+                || approval.updated_at + Duration::minutes(RETENTION_MINUTES) > now
+        });
+        expired
+    }
+}
+
+/// Whether `user` may decide backstage requests: admins may, and so may
+/// users their identity provider marks as approvers
+pub async fn may_approve(app_state: &AppState, user: &AuthUser) -> bool {
+    if authz::user_role(user).is_ok_and(|role| role.is_admin()) {
+        return true;
+    }
+    let Some(db) = &app_state.db else { return false };
+    match db.get_user_by_id(user.user_id).await {
+        Ok(Some(account)) => account.is_active && account.is_approver,
+        Ok(None) => false,
+        Err(e) => {
+            warn!("Failed to look up approver rights of {}: {}", user.user_id, e);
+            false
+        }
+    }
+}
+
+/// Audit entry about `approval`
+pub fn audit(approval: &Approval, action: &str) -> AuditLog {
+    let mut entry = AuditLog::new("session", action)
+        .agent(approval.agent_id)
+        .details(serde_json::json!({
+            "approval_id": approval.id,
+            "requested_by": approval.requested_by,
+            "decided_by": approval.decided_by,
+            "justification": approval.justification,
+            "reason": approval.reason,
+        }));
+    if let Some(session_id) = approval.session_id {
+        entry = entry.session(session_id);
+    }
+    entry
+}
+
+// ============================================================================
+// API Handlers
+// ============================================================================
+
+/// The caller's own requests, and the pending ones they may decide
+pub async fn api_list_approvals(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+) -> Response {
+    let approver = may_approve(&app_state, &user).await;
+    let approvals = app_state.device_manager.approvals.visible_to(tenant, user.user_id, approver).await;
+    Json(serde_json::json!({ "approvals": approvals })).into_response()
+}
+
+/// Where a request stands; its requester also gets the token to attach
+/// to the session once it is approved
+pub async fn api_get_approval(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(approval_id): Path<Uuid>,
+) -> Response {
+    let approval = match app_state.device_manager.approvals.get(approval_id).await {
+        Some(approval) if tenant.includes(approval.organization_id) => approval,
+        _ => return ApprovalError::Unknown.into_response(),
+    };
+    if approval.is_requester(user.user_id) {
+        return Json(serde_json::json!({ "approval": approval, "launch": approval.launch })).into_response();
+    }
+    if !may_approve(&app_state, &user).await {
+        return ApprovalError::Unknown.into_response();
+    }
+    Json(serde_json::json!({ "approval": approval })).into_response()
+}
+
+/// Approve a request, starting the session it holds
+pub async fn api_approve(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Path(approval_id): Path<Uuid>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    match device_manager.approvals.get(approval_id).await {
+        Some(approval) if tenant.includes(approval.organization_id) => {}
+        _ => return ApprovalError::Unknown.into_response(),
+    }
+    if !may_approve(&app_state, &user).await {
+        return ApprovalError::NotApprover.into_response();
+    }
+
+    let approval = match device_manager.approve_backstage(approval_id, user.user_id).await {
+        Ok(approval) => approval,
+        Err(e) => return e.into_response(),
+    };
+    if approval.state == ApprovalState::Failed {
+        device_manager.record_audit(audit(&approval, "backstage_session_failed").actor(user.user_id.to_string()).request(&context)).await;
+        return (StatusCode::CONFLICT, Json(serde_json::json!({
+            "error": approval.reason.clone().unwrap_or_default(),
+            "approval": approval,
+        }))).into_response();
+    }
+    device_manager.record_audit(audit(&approval, "backstage_approved").actor(user.user_id.to_string()).request(&context)).await;
+    Json(serde_json::json!({ "approval": approval })).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DenyRequest {
+    /// Told to the requester
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Deny a request, or withdraw one of the caller's own
+pub async fn api_deny(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Path(approval_id): Path<Uuid>,
+    body: Option<Json<DenyRequest>>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    let withdrawing = match device_manager.approvals.get(approval_id).await {
+        Some(approval) if tenant.includes(approval.organization_id) => approval.is_requester(user.user_id),
+        _ => return ApprovalError::Unknown.into_response(),
+    };
+    if !withdrawing && !may_approve(&app_state, &user).await {
+        return ApprovalError::NotApprover.into_response();
+    }
+    let reason = body
+        .and_then(|Json(body)| body.reason)
+        .map(|reason| reason.trim().chars().take(MAX_JUSTIFICATION_LEN).collect::<String>())
+        .filter(|reason| !reason.is_empty());
+
+    match device_manager.deny_backstage(approval_id, user.user_id, reason).await {
+        Ok(approval) => {
+            let action = if withdrawing { "backstage_request_withdrawn" } else { "backstage_denied" };
+            device_manager.record_audit(audit(&approval, action).actor(user.user_id.to_string()).request(&context)).await;
+            Json(serde_json::json!({ "approval": approval })).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SessionType;
+
+    fn held(user_id: Uuid) -> HeldSession {
+        HeldSession {
+            request: SessionRequest {
+                agent_id: Uuid::new_v4(),
+                session_type: SessionType::Backstage,
+                user_id,
+                capabilities: None,
+                bandwidth_kbps: None,
+                compress_frames: false,
+                ticket_ref: None,
+            },
+            scopes: Vec::new(),
+            token_ttl: Duration::minutes(30),
+            region: None,
+            connection_type: None,
+            access_grant_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_decided_by_someone_else() {
+        let (requester, approver) = (Uuid::new_v4(), Uuid::new_v4());
+        let tracker = ApprovalTracker::new();
+        let now = Utc::now();
+        let ttl = Duration::minutes(15);
+
+        let approval = tracker.request(held(requester), None, requester, "tech@example.com", "Disk full", ttl, now).await;
+        assert_eq!(approval.state, ApprovalState::Pending);
+        let visible = |user_id, approver| tracker.visible_to(Tenant::All, user_id, approver);
+        assert_eq!(visible(requester, false).await.len(), 1);
+        assert_eq!(visible(approver, true).await.len(), 1);
+        assert!(visible(approver, false).await.is_empty());
+
+        // Neither the requester nor the technician it is asked for approves it
+        assert_eq!(tracker.approve(approval.id, requester, now).await.unwrap_err(), ApprovalError::SelfApproval);
+        let on_behalf = tracker.request(held(approver), None, requester, "tech@example.com", "Disk full", ttl, now).await;
+        assert_eq!(tracker.approve(on_behalf.id, approver, now).await.unwrap_err(), ApprovalError::SelfApproval);
+
+        let approved = tracker.approve(approval.id, approver, now).await.unwrap();
+        assert_eq!((approved.state, approved.decided_by), (ApprovalState::Approved, Some(approver)));
+        assert_eq!(tracker.approve(approval.id, approver, now).await.unwrap_err(), ApprovalError::Closed);
+        assert_eq!(tracker.deny(approval.id, approver, None, now).await.unwrap_err(), ApprovalError::Closed);
+        // Decided requests leave the approvers' list, not the requester's
+        assert_eq!(visible(approver, true).await.iter().map(|approval| approval.id).collect::<Vec<_>>(), [on_behalf.id]);
+        assert_eq!(visible(requester, false).await.len(), 2);
+
+        let denied = tracker.deny(on_behalf.id, approver, Some("Use the ticket".to_string()), now).await.unwrap();
+        assert_eq!((denied.state, denied.reason.as_deref()), (ApprovalState::Denied, Some("Use the ticket")));
+    }
+
+    #[tokio::test]
+    async fn test_undecided_requests_expire() {
+        let (requester, approver) = (Uuid::new_v4(), Uuid::new_v4());
+        let tracker = ApprovalTracker::new();
+        let now = Utc::now();
+        let ttl = Duration::minutes(15);
+
+        let approval = tracker.request(held(requester), None, requester, "tech", "Disk full", ttl, now).await;
+        assert!(tracker.expire(now + Duration::minutes(14)).await.is_empty());
+        // Past the deadline it cannot be approved, even before the sweep
+        assert_eq!(tracker.approve(approval.id, approver, now + ttl).await.unwrap_err(), ApprovalError::Expired);
+        let expired = tracker.expire(now + ttl).await;
+        assert_eq!(expired.iter().map(|approval| approval.state).collect::<Vec<_>>(), [ApprovalState::Expired]);
+        assert_eq!(tracker.deny(approval.id, approver, None, now + ttl).await.unwrap_err(), ApprovalError::Expired);
+        assert!(tracker.pending(|_| true).await.is_empty());
+
+        // Ended requests are kept for a while, then dropped
+        tracker.expire(now + ttl + Duration::minutes(RETENTION_MINUTES - 1)).await;
+        assert!(tracker.get(approval.id).await.is_some());
+        tracker.expire(now + ttl + Duration::minutes(RETENTION_MINUTES)).await;
+        assert!(tracker.get(approval.id).await.is_none());
+    }
+}
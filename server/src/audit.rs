@@ -0,0 +1,417 @@
+//! Audit trail of privileged actions
+//!
+//! Handlers describe what happened in an [`AuditLog`] entry, adding the
+//! caller's address and user agent from a [`RequestContext`], and hand it to
+//! `DeviceManager::record_audit`. That redacts secrets from the details and
+//! writes the entry to the database, or to a rotated JSONL file
+//! ([`AuditFile`]) when the server runs without one. Super-admins read the
+//! trail back through `GET /api/audit`; it spans every organization, so
+//! admins of one do not.
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Query, State},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::auth::jwt::AuthUser;
+use crate::models::AuditLog;
+use crate::organizations::Tenant;
+use crate::AppState;
+
+/// Stands in for redacted values
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Detail keys whose values never reach the audit trail, matched as
+/// case-insensitive substrings
+const SECRET_KEYS: &[&str] = &[
+    "password",
+    "passphrase",
+    "secret",
+    "token",
+    "authorization",
+    "cookie",
+    "api_key",
+    "apikey",
+    "private_key",
+    "credential",
+];
+
+/// Default and largest page of `GET /api/audit`
+const DEFAULT_AUDIT_PAGE: usize = 100;
+pub const MAX_AUDIT_PAGE: usize = 1000;
+
+/// Longest user agent kept, in characters
+const MAX_USER_AGENT: usize = 512;
+
+/// Whether values under `key` are secrets
+pub fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SECRET_KEYS.iter().any(|secret| key.contains(secret))
+}
+
+/// Replace the values of secret keys anywhere in `value`
+pub fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_secret_key(key) && !value.is_null() {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Where a request came from, for audit entries
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestContext {
+    pub source_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl RequestContext {
+    /// The client behind the proxy if it says so, otherwise the peer.
+    ///
+    /// The server is deployed behind nginx, which sets `X-Forwarded-For`.
+    pub fn from_parts(parts: &Parts) -> Self {
+        let header = |name: &str| {
+            parts.headers.get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        let source_ip = header("x-forwarded-for")
+            .and_then(|forwarded| forwarded.split(',').next())
+            .map(|ip| ip.trim().to_string())
+            .or_else(|| header("x-real-ip").map(str::to_string))
+            .or_else(|| {
+                parts.extensions.get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| addr.ip().to_canonical().to_string())
+            });
+        let user_agent = header(header::USER_AGENT.as_str())
+            .map(|agent| agent.chars().filter(|c| !c.is_control()).take(MAX_USER_AGENT).collect());
+        Self { source_ip, user_agent }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(parts))
+    }
+}
+
+/// Criteria for reading the audit trail
+#[derive(Debug, Default, Clone)]
+pub struct AuditFilter {
+    /// The acting user, as recorded in `actor`
+    pub user: Option<String>,
+    pub agent_id: Option<Uuid>,
+    pub action: Option<String>,
+    pub category: Option<String>,
+    /// Only entries at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only entries at or before this time
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditLog) -> bool {
+        self.user.as_ref().is_none_or(|user| entry.actor.as_ref() == Some(user))
+            && self.agent_id.is_none_or(|agent_id| entry.agent_id == Some(agent_id))
+            && self.action.as_ref().is_none_or(|action| &entry.action == action)
+            && self.category.as_ref().is_none_or(|category| &entry.category == category)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+    }
+}
+
+/// Where and how much of the audit trail is kept without a database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditFileConfig {
+    pub dir: PathBuf,
+    /// Size at which the live file is rotated
+    pub max_bytes: u64,
+    /// Rotated files kept besides the live one
+    pub keep: usize,
+}
+
+impl Default for AuditFileConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./data/audit"),
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+/// JSONL audit trail for servers without a database.
+///
+/// Entries are appended to `audit.jsonl`; once that outgrows `max_bytes` it
+/// becomes `audit.1.jsonl`, shifting older files up and dropping the one
+/// past `keep`.
+#[derive(Debug)]
+pub struct AuditFile {
+    dir: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    /// Serializes appends and rotation
+    lock: Mutex<()>,
+}
+
+impl AuditFile {
+    pub fn new(config: &AuditFileConfig) -> Self {
+        Self {
+            dir: config.dir.clone(),
+            max_bytes: config.max_bytes,
+            keep: config.keep,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The live file for 0, rotated files from 1
+    fn path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.dir.join("audit.jsonl"),
+            n => self.dir.join(format!("audit.{}.jsonl", n)),
+        }
+    }
+
+    pub async fn append(&self, entry: &AuditLog) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().await;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let current = self.path(0);
+        let size = tokio::fs::metadata(&current).await.map(|meta| meta.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&current).await?;
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+
+    async fn rotate(&self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            return tokio::fs::remove_file(self.path(0)).await;
+        }
+        match tokio::fs::remove_file(self.path(self.keep)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for index in (0..self.keep).rev() {
+            match tokio::fs::rename(self.path(index), self.path(index + 1)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries matching `filter`, newest first
+    pub async fn query(&self, filter: &AuditFilter) -> std::io::Result<Vec<AuditLog>> {
+        let _guard = self.lock.lock().await;
+        let mut matching = Vec::new();
+        // Oldest file first, so reversing at the end puts the newest first
+        for index in (0..=self.keep).rev() {
+            let contents = match tokio::fs::read_to_string(self.path(index)).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                match serde_json::from_str::<AuditLog>(line) {
+                    Ok(entry) if filter.matches(&entry) => matching.push(entry),
+                    Ok(_) => {}
+                    Err(e) => warn!("Skipping unreadable audit entry in {}: {}", self.path(index).display(), e),
+                }
+            }
+        }
+        matching.reverse();
+        Ok(matching)
+    }
+}
+
+/// Filters and pagination of `GET /api/audit`
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub user: Option<String>,
+    pub agent: Option<Uuid>,
+    pub action: Option<String>,
+    pub category: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// Read the audit trail, newest first; super-admins only, as it spans every
+/// organization
+pub async fn api_list_audit(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    match Tenant::of(&user) {
+        Ok(tenant) if tenant.is_super_admin() => {}
+        Ok(_) => {
+            return (StatusCode::FORBIDDEN, Json(serde_json::json!({
+                "error": "Only admins outside an organization can read the audit log"
+            }))).into_response();
+        }
+        Err(e) => return e.into_response(),
+    }
+
+    let filter = AuditFilter {
+        user: query.user,
+        agent_id: query.agent,
+        action: query.action,
+        category: query.category,
+        from: query.from,
+        to: query.to,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_PAGE).min(MAX_AUDIT_PAGE);
+
+    match app_state.device_manager.query_audit(&filter, limit, query.offset).await {
+        Ok((entries, total)) => Json(serde_json::json!({
+            "entries": entries,
+            "total": total,
+            "limit": limit,
+            "offset": query.offset,
+        })).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+            "error": format!("Failed to read the audit log: {}", e)
+        }))).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use std::sync::Arc;
+
+    fn scratch_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("ghostlink-audit-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let mut details = serde_json::json!({
+            "username": "sam",
+            "password": "hunter2",
+            "config": {
+                "client_secret": "abc",
+                "client_id": "ghostlink",
+                "headers": [{ "Authorization": "Bearer xyz" }],
+                "refresh_token": null,
+            },
+        });
+        redact(&mut details);
+        assert_eq!(details, serde_json::json!({
+            "username": "sam",
+            "password": REDACTED,
+            "config": {
+                "client_secret": REDACTED,
+                "client_id": "ghostlink",
+                "headers": [{ "Authorization": REDACTED }],
+                "refresh_token": null,
+            },
+        }));
+    }
+
+    #[test]
+    fn test_request_context_prefers_forwarded_address() {
+        let request = Request::builder()
+            .header("X-Forwarded-For", "203.0.113.7, 10.0.0.2")
+            .header("User-Agent", "Mozilla/5.0")
+            .body(())
+            .unwrap();
+        let (mut parts, _) = request.into_parts();
+        parts.extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 443))));
+        let context = RequestContext::from_parts(&parts);
+        assert_eq!(context.source_ip.as_deref(), Some("203.0.113.7"));
+        assert_eq!(context.user_agent.as_deref(), Some("Mozilla/5.0"));
+
+        parts.headers.clear();
+        assert_eq!(RequestContext::from_parts(&parts).source_ip.as_deref(), Some("10.0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn test_file_rotates_and_reads_back_newest_first() {
+        let file = AuditFile::new(&AuditFileConfig { dir: scratch_dir(), max_bytes: 600, keep: 2 });
+        let agent_id = Uuid::new_v4();
+        let mut written = Vec::new();
+        for i in 0..12 {
+            let mut entry = AuditLog::new("session", if i % 2 == 0 { "created" } else { "ended" })
+                .actor("tech");
+            if i % 3 == 0 {
+                entry = entry.agent(agent_id);
+            }
+            file.append(&entry).await.unwrap();
+            written.push(entry);
+        }
+
+        // Older entries fell off with the file past `keep`
+        assert!(file.path(2).exists());
+        assert!(!file.path(3).exists());
+        let all = file.query(&AuditFilter::default()).await.unwrap();
+        assert!(all.len() < written.len());
+        assert_eq!(all[0].id, written[11].id);
+        assert!(all.windows(2).all(|pair| pair[0].timestamp >= pair[1].timestamp));
+
+        let filter = AuditFilter { agent_id: Some(agent_id), action: Some("ended".to_string()), ..Default::default() };
+        let matching = file.query(&filter).await.unwrap();
+        assert_eq!(matching.iter().map(|entry| entry.id).collect::<Vec<_>>(), [written[9].id]);
+
+        tokio::fs::remove_dir_all(file.dir()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recorded_entries_are_redacted() {
+        let file = Arc::new(AuditFile::new(&AuditFileConfig { dir: scratch_dir(), ..Default::default() }));
+        let device_manager = crate::device_manager::DeviceManager::new().with_audit_file(file.clone());
+        device_manager.record_audit(
+            AuditLog::new("config", "oidc_config_updated")
+                .actor("admin")
+                .details(serde_json::json!({ "client_id": "ghostlink", "client_secret": "s3cr3t" })),
+        ).await;
+
+        let stored = tokio::fs::read_to_string(file.path(0)).await.unwrap();
+        assert!(!stored.contains("s3cr3t"));
+        let (entries, total) = device_manager.query_audit(&AuditFilter::default(), 10, 0).await.unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(entries[0].details.0["client_secret"], REDACTED);
+        assert_eq!(entries[0].details.0["client_id"], "ghostlink");
+
+        tokio::fs::remove_dir_all(file.dir()).await.unwrap();
+    }
+}
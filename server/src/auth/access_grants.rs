@@ -0,0 +1,609 @@
+//! Temporary access grants for third-party technicians
+//!
+//! An admin or operator grants an email address access to one device until
+//! an expiry, with a subset of the session scopes (view, control, file,
+//! terminal). The grant comes with a signed link; opening it exchanges the
+//! link for a token of a guest identity, whose user ID is the grant's ID, so
+//! everything the guest does is audited under the grant. Guests see only the
+//! granted device and start sessions on it within the grant's scopes.
+//! Revoking a grant, or its expiry, ends every session started under it.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::authz;
+use crate::auth::jwt::{error, AuthError, AuthUser, JwtService};
+use crate::auth::session_tokens::SessionScope;
+use crate::models::{AuditLog, SessionType, UserRole};
+use crate::organizations::{self, Tenant};
+use crate::AppState;
+
+/// How often grants that ended are checked for sessions still open
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// `purpose` claim that distinguishes grant links from other tokens
+const LINK_PURPOSE: &str = "access-grant";
+
+/// Lifetime of a grant when the request names none
+const DEFAULT_TTL_MINUTES: i64 = 4 * 60;
+
+/// Longest lifetime a grant may have
+pub const MAX_TTL_MINUTES: i64 = 30 * 24 * 60;
+
+/// How long an ended grant is kept, so its guest is told why it stopped
+/// working instead of that it never existed
+const RETENTION_HOURS: i64 = 24;
+
+/// Longest email address, per RFC 5321
+const MAX_EMAIL_LENGTH: usize = 254;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessGrant {
+    pub id: Uuid,
+    /// The one device the guest may reach
+    pub agent_id: Uuid,
+    /// Organization of the device
+    pub organization_id: Option<Uuid>,
+    /// Who the grant was issued to
+    pub email: String,
+    pub scopes: Vec<SessionScope>,
+    /// Admin or operator who created it
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Sessions the guest started under it, first session first
+    pub sessions: Vec<Uuid>,
+    /// Set once the sessions were ended after the grant ended
+    #[serde(skip)]
+    sessions_closed: bool,
+}
+
+impl AccessGrant {
+    /// Why the grant no longer lets its guest in, if it does not
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), GrantError> {
+        if self.revoked_at.is_some() {
+            Err(GrantError::Revoked)
+        } else if now >= self.expires_at {
+            Err(GrantError::Expired)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn allows(&self, scope: SessionScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Narrow the `scopes` of a new session of `session_type` to the
+    /// grant. The session type needs [`required_scope`]; scopes the guest
+    /// asked for must all be granted, while defaults not granted are dropped.
+    pub fn session_scopes(
+        &self,
+        session_type: &SessionType,
+        scopes: Vec<SessionScope>,
+        requested: bool,
+    ) -> Result<Vec<SessionScope>, GrantError> {
+        let required = required_scope(session_type);
+        if !self.allows(required) {
+            return Err(GrantError::NotAllowed(required));
+        }
+        if let Some(scope) = scopes.iter().find(|scope| requested && !self.allows(**scope)) {
+            return Err(GrantError::NotAllowed(*scope));
+        }
+        Ok(scopes.into_iter().filter(|scope| self.allows(*scope)).collect())
+    }
+}
+
+/// Scope a guest's grant must include to start a session of `session_type`
+pub fn required_scope(session_type: &SessionType) -> SessionScope {
+    match session_type {
+        SessionType::View => SessionScope::View,
+        SessionType::FileTransfer => SessionScope::File,
+        _ => SessionScope::Control,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrantError {
+    Unknown,
+    /// The link is not one this server signed
+    InvalidLink,
+    Expired,
+    Revoked,
+    /// The grant does not include the scope
+    NotAllowed(SessionScope),
+}
+
+impl GrantError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            GrantError::Unknown => StatusCode::NOT_FOUND,
+            GrantError::InvalidLink | GrantError::Expired | GrantError::Revoked => StatusCode::UNAUTHORIZED,
+            GrantError::NotAllowed(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl std::fmt::Display for GrantError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrantError::Unknown => write!(f, "access grant not found"),
+            GrantError::InvalidLink => write!(f, "invalid access link"),
+            GrantError::Expired => write!(f, "access grant expired"),
+            GrantError::Revoked => write!(f, "access grant was revoked"),
+            GrantError::NotAllowed(scope) => write!(f, "access grant does not allow {:?}", scope),
+        }
+    }
+}
+
+impl IntoResponse for GrantError {
+    fn into_response(self) -> Response {
+        error(self.status(), self.to_string())
+    }
+}
+
+impl From<GrantError> for AuthError {
+    fn from(e: GrantError) -> Self {
+        match e {
+            GrantError::Expired => AuthError::TokenExpired,
+            GrantError::Unknown | GrantError::InvalidLink | GrantError::Revoked => AuthError::InvalidToken,
+            GrantError::NotAllowed(_) => AuthError::Forbidden(e.to_string()),
+        }
+    }
+}
+
+/// What a grant link vouches for
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkClaims {
+    purpose: String,
+    grant_id: Uuid,
+    iat: i64,
+    exp: i64,
+}
+
+/// Grants that are live or ended recently, and the key their links are
+/// signed with
+pub struct AccessGrants {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    grants: RwLock<HashMap<Uuid, AccessGrant>>,
+}
+
+impl AccessGrants {
+    pub fn new(secret: &str) -> Self {
+        let secret = format!("ghostlink-access-grants:{}", secret);
+        Self {
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            grants: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Grant `email` access to `agent_id` for `ttl`, returning the grant and
+    /// the token of its link
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        &self,
+        agent_id: Uuid,
+        organization_id: Option<Uuid>,
+        email: String,
+        scopes: Vec<SessionScope>,
+        created_by: Uuid,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<(AccessGrant, String), GrantError> {
+        let grant = AccessGrant {
+            id: Uuid::new_v4(),
+            agent_id,
+            organization_id,
+            email,
+            scopes,
+            created_by,
+            created_at: now,
+            expires_at: now + ttl,
+            revoked_at: None,
+            sessions: Vec::new(),
+            sessions_closed: false,
+        };
+        let claims = LinkClaims {
+            purpose: LINK_PURPOSE.to_string(),
+            grant_id: grant.id,
+            iat: now.timestamp(),
+            exp: grant.expires_at.timestamp(),
+        };
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .map_err(|_| GrantError::InvalidLink)?;
+
+        let mut grants = self.grants.write().await;
+        grants.retain(|_, grant| {
+            let ended = grant.revoked_at.unwrap_or(grant.expires_at).min(grant.expires_at);
+            ended + Duration::hours(RETENTION_HOURS) > now || !grant.sessions_closed
+        });
+        grants.insert(grant.id, grant.clone());
+        Ok((grant, token))
+    }
+
+    /// Grants of `tenant` that still let their guest in, on `agent_id` when
+    /// given, oldest first
+    pub async fn active(&self, tenant: Tenant, agent_id: Option<Uuid>, now: DateTime<Utc>) -> Vec<AccessGrant> {
+        let mut active: Vec<AccessGrant> = self
+            .grants
+            .read()
+            .await
+            .values()
+            .filter(|grant| tenant.includes(grant.organization_id) && grant.check(now).is_ok())
+            .filter(|grant| agent_id.is_none_or(|agent_id| grant.agent_id == agent_id))
+            .cloned()
+            .collect();
+        active.sort_by_key(|grant| grant.created_at);
+        active
+    }
+
+    /// Grant `id` while it still lets its guest in
+    pub async fn live(&self, id: Uuid, now: DateTime<Utc>) -> Result<AccessGrant, GrantError> {
+        let grant = self.grants.read().await.get(&id).cloned().ok_or(GrantError::Unknown)?;
+        grant.check(now)?;
+        Ok(grant)
+    }
+
+    /// The live grant a link `token` was issued for
+    pub async fn redeem_link(&self, token: &str, now: DateTime<Utc>) -> Result<AccessGrant, GrantError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = decode::<LinkClaims>(token, &self.decoding_key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => GrantError::Expired,
+                _ => GrantError::InvalidLink,
+            })?
+            .claims;
+        if claims.purpose != LINK_PURPOSE {
+            return Err(GrantError::InvalidLink);
+        }
+        self.live(claims.grant_id, now).await
+    }
+
+    /// Remember that the guest of grant `id` started `session_id`
+    pub async fn attach_session(&self, id: Uuid, session_id: Uuid) {
+        if let Some(grant) = self.grants.write().await.get_mut(&id) {
+            grant.sessions.push(session_id);
+        }
+    }
+
+    /// Revoke grant `id` of `tenant`; revoking it again changes nothing
+    pub async fn revoke(&self, tenant: Tenant, id: Uuid, now: DateTime<Utc>) -> Result<AccessGrant, GrantError> {
+        let mut grants = self.grants.write().await;
+        let grant = grants
+            .get_mut(&id)
+            .filter(|grant| tenant.includes(grant.organization_id))
+            .ok_or(GrantError::Unknown)?;
+        grant.revoked_at.get_or_insert(now);
+        Ok(grant.clone())
+    }
+
+    /// Grants that ended since the last call, each with the sessions started
+    /// under it, which are to be ended
+    pub async fn take_ended(&self, now: DateTime<Utc>) -> Vec<AccessGrant> {
+        let mut grants = self.grants.write().await;
+        grants
+            .values_mut()
+            .filter(|grant| !grant.sessions_closed && grant.check(now).is_err())
+            .map(|grant| {
+                grant.sessions_closed = true;
+                grant.clone()
+            })
+            .collect()
+    }
+}
+
+/// The grant of a guest caller, checked to still let them in; `None` for
+/// every other role
+pub async fn guest_grant(app_state: &AppState, user: &AuthUser) -> Result<Option<AccessGrant>, AuthError> {
+    if authz::user_role(user)? != UserRole::Guest {
+        return Ok(None);
+    }
+    let grant = app_state.device_manager.access_grants.live(user.user_id, Utc::now()).await?;
+    Ok(Some(grant))
+}
+
+/// Trimmed, lowercased email address, or why it can't be used
+pub fn normalize_email(email: &str) -> Result<String, String> {
+    let email = email.trim().to_lowercase();
+    let valid = email.len() <= MAX_EMAIL_LENGTH
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty() && domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.') && !domain.contains('@')
+        });
+    if !valid {
+        return Err(format!("Invalid email address '{}'", email));
+    }
+    Ok(email)
+}
+
+/// Where the guest of a grant signs in with `token`
+pub fn link(token: &str) -> String {
+    format!("/api/auth/guest?token={}", token)
+}
+
+/// Request body for granting access to a device
+#[derive(Debug, Deserialize)]
+pub struct CreateAccessGrantRequest {
+    pub email: String,
+    /// Minutes until the grant expires; `expires_at` may be given instead
+    #[serde(default)]
+    pub ttl_minutes: Option<i64>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// What the guest may do; viewing only when unset
+    #[serde(default, alias = "scopes")]
+    pub capabilities: Option<Vec<SessionScope>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessGrant {
+    #[serde(flatten)]
+    pub grant: AccessGrant,
+    /// Signed link for the guest; whoever holds it signs in as the guest
+    pub link: String,
+}
+
+/// Grant an email address temporary access to a device (admins and
+/// operators)
+pub async fn api_create_access_grant(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Path(agent_id): Path<String>,
+    Json(request): Json<CreateAccessGrantRequest>,
+) -> Response {
+    let Ok(agent_id) = Uuid::parse_str(&agent_id) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid agent ID format");
+    };
+    let device_manager = &app_state.device_manager;
+    let agent = device_manager.get_agent(agent_id).await
+        .map(|(agent, _)| agent)
+        .filter(|agent| tenant.includes(agent.organization_id));
+    let Some(agent) = agent else {
+        return organizations::device_not_found(agent_id).into_response();
+    };
+    let email = match normalize_email(&request.email) {
+        Ok(email) => email,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let now = Utc::now();
+    let ttl = match (request.expires_at, request.ttl_minutes) {
+        (Some(expires_at), None) => expires_at - now,
+        (None, ttl_minutes) => Duration::minutes(ttl_minutes.unwrap_or(DEFAULT_TTL_MINUTES)),
+        (Some(_), Some(_)) => return error(StatusCode::BAD_REQUEST, "Give either ttl_minutes or expires_at"),
+    };
+    if ttl < Duration::minutes(1) || ttl > Duration::minutes(MAX_TTL_MINUTES) {
+        return error(
+            StatusCode::BAD_REQUEST,
+            format!("A grant must last between 1 and {} minutes", MAX_TTL_MINUTES),
+        );
+    }
+    let mut scopes = request.capabilities.unwrap_or_else(|| vec![SessionScope::View]);
+    if scopes.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "At least one capability is required");
+    }
+    // Every session can be watched
+    if !scopes.contains(&SessionScope::View) {
+        scopes.insert(0, SessionScope::View);
+    }
+    scopes.dedup();
+
+    let created = device_manager.access_grants
+        .create(agent_id, agent.organization_id, email, scopes, user.user_id, ttl, now)
+        .await;
+    let (grant, token) = match created {
+        Ok(created) => created,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    device_manager.record_audit(
+        AuditLog::new("auth", "access_grant_created")
+            .actor(user.user_id.to_string())
+            .agent(agent_id)
+            .request(&context)
+            .details(serde_json::json!({
+                "grant_id": grant.id,
+                "email": grant.email,
+                "scopes": grant.scopes,
+                "expires_at": grant.expires_at,
+            })),
+    ).await;
+    info!("Access grant {} to device {} created by {} for {}", grant.id, agent_id, user.email, grant.email);
+    (StatusCode::CREATED, Json(CreatedAccessGrant { grant, link: link(&token) })).into_response()
+}
+
+/// Grants of the caller's organization that still let their guest in
+pub async fn api_list_access_grants(State(app_state): State<AppState>, tenant: Tenant) -> Response {
+    let grants = app_state.device_manager.access_grants.active(tenant, None, Utc::now()).await;
+    Json(serde_json::json!({ "grants": grants })).into_response()
+}
+
+/// Grants on one device that still let their guest in
+pub async fn api_list_device_access_grants(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let Ok(agent_id) = Uuid::parse_str(&agent_id) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid agent ID format");
+    };
+    if !crate::api::in_tenant(&app_state, tenant, agent_id).await {
+        return organizations::device_not_found(agent_id).into_response();
+    }
+    let grants = app_state.device_manager.access_grants.active(tenant, Some(agent_id), Utc::now()).await;
+    Json(serde_json::json!({ "grants": grants })).into_response()
+}
+
+/// Revoke a grant, ending every session its guest has open
+pub async fn api_revoke_access_grant(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Path(grant_id): Path<String>,
+) -> Response {
+    let Ok(grant_id) = Uuid::parse_str(&grant_id) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid grant ID format");
+    };
+    match app_state.device_manager.revoke_access_grant(tenant, grant_id, Utc::now()).await {
+        Ok((grant, ended)) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("auth", "access_grant_revoked")
+                    .actor(user.user_id.to_string())
+                    .agent(grant.agent_id)
+                    .request(&context)
+                    .details(serde_json::json!({ "grant_id": grant.id, "ended_sessions": ended })),
+            ).await;
+            Json(grant).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GuestLoginQuery {
+    pub token: String,
+}
+
+/// Sign in as the guest of the grant a link was issued for. The guest's
+/// token expires with the grant, and stops working when it is revoked.
+pub async fn api_guest_login(
+    State(app_state): State<AppState>,
+    context: RequestContext,
+    Query(query): Query<GuestLoginQuery>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    let now = Utc::now();
+    let grant = match device_manager.access_grants.redeem_link(&query.token, now).await {
+        Ok(grant) => grant,
+        Err(e) => {
+            device_manager.record_audit(
+                AuditLog::new("auth", "guest_login_failed")
+                    .request(&context)
+                    .details(serde_json::json!({ "reason": e.to_string() })),
+            ).await;
+            return e.into_response();
+        }
+    };
+
+    let org_id = grant.organization_id.map(|id| id.to_string());
+    let token = JwtService::new(&app_state.config.jwt_secret)
+        .generate_guest_token(&grant.id, &grant.email, org_id.as_deref(), grant.expires_at);
+    let access_token = match token {
+        Ok(token) => token,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    device_manager.record_audit(
+        AuditLog::new("auth", "guest_login_succeeded")
+            .actor(grant.id.to_string())
+            .agent(grant.agent_id)
+            .request(&context)
+            .details(serde_json::json!({ "email": grant.email })),
+    ).await;
+    Json(serde_json::json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": (grant.expires_at - now).num_seconds(),
+        "grant": grant,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn grant(grants: &AccessGrants, scopes: Vec<SessionScope>, now: DateTime<Utc>) -> (AccessGrant, String) {
+        grants
+            .create(Uuid::new_v4(), None, "vendor@example.com".to_string(), scopes, Uuid::new_v4(), Duration::hours(1), now)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_links_sign_in_until_the_grant_expires() {
+        let grants = AccessGrants::new("test-secret");
+        let now = Utc::now();
+        let (created, token) = grant(&grants, vec![SessionScope::View], now).await;
+
+        assert_eq!(grants.redeem_link(&token, now).await.unwrap().id, created.id);
+        assert_eq!(grants.redeem_link("not-a-token", now).await.unwrap_err(), GrantError::InvalidLink);
+        let (_, foreign) = grant(&AccessGrants::new("other-secret"), vec![SessionScope::View], now).await;
+        assert_eq!(grants.redeem_link(&foreign, now).await.unwrap_err(), GrantError::InvalidLink);
+
+        let later = now + Duration::hours(1);
+        assert_eq!(grants.live(created.id, later).await.unwrap_err(), GrantError::Expired);
+        assert!(grants.active(Tenant::All, None, later).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_revoked_grants_hand_over_their_sessions_once() {
+        let grants = AccessGrants::new("test-secret");
+        let now = Utc::now();
+        let acme = Uuid::new_v4();
+        let (created, token) = grants
+            .create(Uuid::new_v4(), Some(acme), "vendor@example.com".to_string(), vec![SessionScope::View], Uuid::new_v4(), Duration::hours(1), now)
+            .await
+            .unwrap();
+        let session_id = Uuid::new_v4();
+        grants.attach_session(created.id, session_id).await;
+        assert!(grants.take_ended(now).await.is_empty());
+
+        let elsewhere = Tenant::Organization(Some(Uuid::new_v4()));
+        assert_eq!(grants.revoke(elsewhere, created.id, now).await.unwrap_err(), GrantError::Unknown);
+        let revoked = grants.revoke(Tenant::Organization(Some(acme)), created.id, now).await.unwrap();
+        assert_eq!(revoked.revoked_at, Some(now));
+        assert_eq!(grants.redeem_link(&token, now).await.unwrap_err(), GrantError::Revoked);
+
+        let ended = grants.take_ended(now).await;
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].sessions, [session_id]);
+        assert!(grants.take_ended(now).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_stay_within_the_grant() {
+        let grants = AccessGrants::new("test-secret");
+        let (viewing, _) = grant(&grants, vec![SessionScope::View, SessionScope::File], Utc::now()).await;
+
+        let all = vec![SessionScope::View, SessionScope::Control, SessionScope::File, SessionScope::Terminal];
+        assert_eq!(
+            viewing.session_scopes(&SessionType::Console, all.clone(), false),
+            Err(GrantError::NotAllowed(SessionScope::Control))
+        );
+        let files = viewing.session_scopes(&SessionType::FileTransfer, vec![SessionScope::View, SessionScope::File], false);
+        assert_eq!(files, Ok(vec![SessionScope::View, SessionScope::File]));
+        assert_eq!(
+            viewing.session_scopes(&SessionType::View, vec![SessionScope::View, SessionScope::Terminal], true),
+            Err(GrantError::NotAllowed(SessionScope::Terminal))
+        );
+
+        let (control, _) = grant(&grants, vec![SessionScope::View, SessionScope::Control], Utc::now()).await;
+        let defaults = control.session_scopes(&SessionType::Console, all, false);
+        assert_eq!(defaults, Ok(vec![SessionScope::View, SessionScope::Control]));
+    }
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(normalize_email(" Vendor@Example.com ").unwrap(), "vendor@example.com");
+        for invalid in ["vendor", "@example.com", "vendor@localhost", "a b@example.com", "vendor@example.com."] {
+            assert!(normalize_email(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::auth::access_grants;
+use crate::auth::jwt::{AuthError, AuthUser};
+use crate::auth::session_tokens::SessionScope;
+use crate::models::UserRole;
+use crate::organizations::{self, Tenant};
+use crate::AppState;
+
+/// Routes reachable without a token
+const PUBLIC_ROUTES: &[&str] = &[
+    "/api/auth/login",
+    "/api/auth/refresh",
+    // Guests sign in with the link of their access grant
+    "/api/auth/guest",
+    "/api/auth/oidc/login",
+    "/api/auth/oidc/callback",
+    "/api/auth/oidc/oauth-callback",
+    "/api/auth/oidc/auth-url",
+    "/api/auth/oidc/validate",
+    "/api/auth/oidc/logout",
+    "/api/auth/oidc/nginx",
+    "/api/branding/theme.css",
+    // Viewers attach with a session token instead of a user token
+    "/api/ws",
+    "/api/session-tokens/key",
+    "/metrics",
+];
+
+/// Server configuration endpoints, writable by admins only
+const ADMIN_CONFIG_ROUTES: &[&str] = &[
+    "/api/auth/oidc/config",
+    "/api/branding/config",
+    "/api/vpn/config",
+];
+
+/// Access level a route requires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    /// No authentication required
+    Public,
+    /// Any authenticated role; read-only endpoints and the caller's own account
+    Authenticated,
+    /// Starting, controlling or ending sessions and other state-changing calls
+    SessionControl,
+    /// Granting others temporary access to devices
+    Delegation,
+    /// Watching recordings of what others did on devices
+    Review,
+    /// User management and server configuration
+    Administration,
+}
+
+/// Map a request to the access level it requires
+pub fn classify_route(method: &Method, path: &str) -> RouteClass {
+    if PUBLIC_ROUTES.contains(&path) {
+        return RouteClass::Public;
+    }
+
+    if path == "/api/users" || path.starts_with("/api/users/") {
+        return RouteClass::Administration;
+    }
+
+    let is_read = method == Method::GET || method == Method::HEAD;
+
+    if ADMIN_CONFIG_ROUTES.contains(&path) && !is_read {
+        return RouteClass::Administration;
+    }
+    if path.starts_with("/api/pam/elevation/") && path.ends_with("/approve") {
+        return RouteClass::Administration;
+    }
+    if path == "/api/devices/enroll-tokens" {
+        return RouteClass::Administration;
+    }
+    // Resetting a device's key lets anything holding a token enroll as it
+    if path.starts_with("/api/devices/") && path.ends_with("/enrollment") {
+        return RouteClass::Administration;
+    }
+    // Invitations carry enrollment tokens too
+    if path == "/api/invitations" || path.starts_with("/api/invitations/") {
+        return RouteClass::Administration;
+    }
+    // Access grants let someone without an account into a device
+    if path == "/api/grants" || path.starts_with("/api/grants/") {
+        return RouteClass::Delegation;
+    }
+    if path.starts_with("/api/devices/") && path.ends_with("/grants") {
+        return RouteClass::Delegation;
+    }
+    // WireGuard peer configs carry private keys, and enabling Tailscale
+    // reconfigures the server
+    if path == "/api/vpn/wireguard/config" || path == "/api/vpn/tailscale/enable" {
+        return RouteClass::Administration;
+    }
+    // Policies change what every device they cover allows
+    if path == "/api/policies" || path.starts_with("/api/policies/") {
+        return RouteClass::Administration;
+    }
+    // Webhooks send event data and their secrets off the server
+    if path == "/api/webhooks" || path.starts_with("/api/webhooks/") {
+        return RouteClass::Administration;
+    }
+    // Custom fields are defined once for the whole organization
+    if (path == "/api/custom-fields" || path.starts_with("/api/custom-fields/")) && !is_read {
+        return RouteClass::Administration;
+    }
+    // Note templates too
+    if (path == "/api/note-templates" || path.starts_with("/api/note-templates/")) && !is_read {
+        return RouteClass::Administration;
+    }
+    // Members may look up their organizations; changing them is for admins,
+    // and the handlers leave it to super-admins
+    if (path == "/api/organizations" || path.starts_with("/api/organizations/")) && !is_read {
+        return RouteClass::Administration;
+    }
+    // The audit trail names users, addresses and commands
+    if path == "/api/audit" || path == "/api/pam/audit" {
+        return RouteClass::Administration;
+    }
+
+    // Agents poll for updates without a user token; they trust a release
+    // by its signature, not by where it came from
+    if path == "/api/releases" {
+        return RouteClass::Administration;
+    }
+    if path.starts_with("/api/releases/") && is_read {
+        return RouteClass::Public;
+    }
+
+    // Relay nodes present the node secret instead of a user token; the node
+    // list shows the network layout and is for admins
+    if path == "/api/relay-nodes/register" || path == "/api/relay-nodes/heartbeat" {
+        return RouteClass::Public;
+    }
+    if path == "/api/relay-nodes" {
+        return RouteClass::Administration;
+    }
+
+    // Agents sign diagnostics uploads with their device key
+    if method == Method::POST && path.starts_with("/api/devices/") && path.ends_with("/diagnostics") {
+        return RouteClass::Public;
+    }
+
+    // Agents sign crash reports like diagnostics
+    if method == Method::POST && path == "/api/crash-reports" {
+        return RouteClass::Public;
+    }
+
+    // Agents sign recording uploads like diagnostics; watching recordings
+    // is for those who oversee technicians
+    if path == "/api/recordings" || path.starts_with("/api/recordings/") {
+        if method == Method::POST && path == "/api/recordings" {
+            return RouteClass::Public;
+        }
+        if method == Method::PUT && path.contains("/chunks/") {
+            return RouteClass::Public;
+        }
+        return RouteClass::Review;
+    }
+
+    // Approvers need not be able to run sessions themselves; the handlers
+    // check who may decide backstage requests
+    if path.starts_with("/api/approvals/") {
+        return RouteClass::Authenticated;
+    }
+
+    // The caller's own session handling is open to every role
+    if path.starts_with("/api/auth/") {
+        return RouteClass::Authenticated;
+    }
+
+    // Terminal sockets execute commands even though they open with a GET
+    if path.starts_with("/api/terminal/") && path.ends_with("/ws") {
+        return RouteClass::SessionControl;
+    }
+
+    if is_read {
+        RouteClass::Authenticated
+    } else {
+        RouteClass::SessionControl
+    }
+}
+
+/// Check that `role` may access routes of `class`
+pub fn check_role(role: &UserRole, class: RouteClass) -> Result<(), AuthError> {
+    let allowed = match class {
+        RouteClass::Public | RouteClass::Authenticated => true,
+        RouteClass::SessionControl => role.can_control_sessions(),
+        RouteClass::Delegation | RouteClass::Review => matches!(role, UserRole::Admin | UserRole::Operator),
+        RouteClass::Administration => role.is_admin(),
+    };
+
+    if allowed {
+        return Ok(());
+    }
+
+    let required = match class {
+        RouteClass::Administration => "admin",
+        RouteClass::Delegation | RouteClass::Review => "admin or operator",
+        _ => "admin, operator or technician",
+    };
+    Err(AuthError::Forbidden(format!(
+        "Role '{}' is not permitted to perform this action (requires {})",
+        role, required
+    )))
+}
+
+/// Check that a guest may call a route. Guests only see their account,
+/// the device of their access grant and the sessions they start on it;
+/// which device and what they may do there is checked by the handlers.
+pub fn check_guest_route(method: &Method, path: &str) -> Result<(), AuthError> {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let allowed = matches!(
+        (method, segments.as_slice()),
+        (&Method::GET, ["api", "auth", "me"])
+            | (&Method::POST, ["api", "auth", "logout"])
+            | (&Method::GET, ["api", "devices"])
+            | (&Method::GET, ["api", "devices", _])
+            | (&Method::POST, ["api", "devices", _, "sessions"])
+            | (&Method::GET, ["api", "approvals", _])
+            | (&Method::DELETE, ["api", "sessions", _])
+            // The close-out note a policy may ask for before that
+            | (&Method::GET, ["api", "note-templates"])
+            | (&Method::POST, ["api", "sessions", _, "notes"])
+    );
+    if allowed {
+        return Ok(());
+    }
+    Err(AuthError::Forbidden("Guests may only use the device they were granted access to".to_string()))
+}
+
+/// Parse the role carried in a user's token
+pub fn user_role(user: &AuthUser) -> Result<UserRole, AuthError> {
+    user.role.parse::<UserRole>().map_err(AuthError::Forbidden)
+}
+
+/// Middleware enforcing the route class of every API request
+pub async fn require_route_permission(request: Request, next: Next) -> Response {
+    let class = classify_route(request.method(), request.uri().path());
+    if class == RouteClass::Public {
+        return next.run(request).await;
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let user = match AuthUser::from_request_parts(&mut parts, &()).await {
+        Ok(user) => user,
+        Err(e) => return e.into_response(),
+    };
+
+    let allowed = user_role(&user).and_then(|role| match role {
+        UserRole::Guest => check_guest_route(&parts.method, parts.uri.path()),
+        role => check_role(&role, class),
+    });
+    if let Err(e) = allowed {
+        warn!("Denied {} {} to user {} ({})", parts.method, parts.uri.path(), user.user_id, user.role);
+        return e.into_response();
+    }
+
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Check a user's grant on a specific agent.
+///
+/// Agents of another organization are reported as not found, to admins too.
+/// Admins are not subject to grants, and without a database there are no
+/// grants to consult, so only the role check applies. Guests reach only the
+/// device of their access grant, and need a grant that allows more than
+/// viewing for anything else; which scopes exactly is up to the caller.
+pub async fn check_agent_permission(
+    app_state: &AppState,
+    user: &AuthUser,
+    agent_id: Uuid,
+    needs_control: bool,
+) -> Result<(), AuthError> {
+    let role = user_role(user)?;
+    if let Some(grant) = access_grants::guest_grant(app_state, user).await? {
+        if grant.agent_id != agent_id {
+            return Err(organizations::device_not_found(agent_id));
+        }
+        if needs_control && grant.scopes.iter().all(|scope| *scope == SessionScope::View) {
+            return Err(AuthError::Forbidden(format!(
+                "Access grant only allows viewing agent {}", agent_id
+            )));
+        }
+        return Ok(());
+    }
+    let tenant = Tenant::of(user)?;
+    if let Some((agent, _)) = app_state.device_manager.get_agent(agent_id).await {
+        if !tenant.includes(agent.organization_id) {
+            return Err(organizations::device_not_found(agent_id));
+        }
+    }
+    if role.is_admin() {
+        return Ok(());
+    }
+
+    let Some(db) = app_state.db.as_ref() else {
+        return Ok(());
+    };
+
+    let permission = db.get_agent_permission(user.user_id, agent_id)
+        .await
+        .map_err(|_| AuthError::Forbidden("Unable to verify agent permissions".to_string()))?;
+
+    // Without a grant of its own, the agent's device group may be one the
+    // user was given through their OIDC claims
+    let permission = match permission {
+        Some(p) => Some((p.can_view || p.can_control, p.can_control)),
+        None => db.user_reaches_agent_through_group(user.user_id, agent_id)
+            .await
+            .map_err(|_| AuthError::Forbidden("Unable to verify agent permissions".to_string()))?
+            .then(|| (true, role.can_control_sessions())),
+    };
+
+    match permission {
+        Some((_, can_control)) if needs_control && can_control => Ok(()),
+        Some((can_view, _)) if !needs_control && can_view => Ok(()),
+        Some(_) if needs_control => Err(AuthError::Forbidden(format!(
+            "Missing can_control permission for agent {}", agent_id
+        ))),
+        _ => Err(AuthError::Forbidden(format!(
+            "Missing can_view permission for agent {}", agent_id
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::jwt::JwtService;
+    use axum::{
+        body::Body,
+        http::StatusCode,
+        middleware,
+        routing::{delete, get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/api/devices", get(|| async { "ok" }))
+            .route("/api/devices/:id/sessions", post(|| async { "ok" }))
+            .route("/api/sessions/:id", delete(|| async { "ok" }))
+            .route("/api/users", post(|| async { "ok" }))
+            .route("/api/auth/login", post(|| async { "ok" }))
+            .route_layer(middleware::from_fn(require_route_permission))
+    }
+
+    fn token(role: &str) -> String {
+        let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
+        JwtService::new(&secret)
+            .generate_access_token(&Uuid::new_v4(), "tech@example.com", role, None, &[])
+            .unwrap()
+    }
+
+    async fn status(method: Method, uri: &str, role: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(role) = role {
+            request = request.header("Authorization", format!("Bearer {}", token(role)));
+        }
+        app().oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_role_route_matrix() {
+        let agent = Uuid::new_v4();
+        let routes = [
+            (Method::GET, "/api/devices".to_string()),
+            (Method::POST, format!("/api/devices/{}/sessions", agent)),
+            (Method::DELETE, format!("/api/sessions/{}", Uuid::new_v4())),
+            (Method::POST, "/api/users".to_string()),
+        ];
+        // Expected success per route, in the order above
+        let matrix = [
+            ("admin", [true, true, true, true]),
+            ("operator", [true, true, true, false]),
+            ("technician", [true, true, true, false]),
+            ("user", [true, false, false, false]),
+            ("viewer", [true, false, false, false]),
+            // Whether guests reach the device is up to their grant
+            ("guest", [true, true, true, false]),
+        ];
+
+        for (role, expected) in matrix {
+            for ((method, uri), allowed) in routes.iter().zip(expected) {
+                let want = if allowed { StatusCode::OK } else { StatusCode::FORBIDDEN };
+                assert_eq!(status(method.clone(), uri, Some(role)).await, want, "{} {} as {}", method, uri, role);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_token_and_public_routes() {
+        assert_eq!(status(Method::GET, "/api/devices", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Method::POST, "/api/auth/login", None).await, StatusCode::OK);
+        assert_eq!(status(Method::GET, "/api/devices", Some("intern")).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrades_take_query_token() {
+        let uri = format!("/api/devices?access_token={}", token("viewer"));
+        let upgrade = Request::builder()
+            .uri(&uri)
+            .header("Upgrade", "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app().oneshot(upgrade).await.unwrap().status(), StatusCode::OK);
+
+        // Plain requests still need the header, so tokens stay out of logs
+        assert_eq!(status(Method::GET, &uri, None).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_body_names_missing_permission() {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/users")
+            .header("Authorization", format!("Bearer {}", token("operator")))
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(json["error"].as_str().unwrap().contains("requires admin"));
+    }
+
+    #[test]
+    fn test_route_classes() {
+        assert_eq!(classify_route(&Method::GET, "/api/vpn/config"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::PUT, "/api/vpn/config"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/vpn/wireguard/config"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/vpn/peers"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::POST, "/api/auth/logout"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::GET, "/api/audit"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/pam/audit"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/organizations"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::POST, "/api/organizations"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/terminal/abc/ws"), RouteClass::SessionControl);
+        assert_eq!(classify_route(&Method::POST, "/api/pam/elevation/abc/approve"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::POST, "/api/approvals/abc/approve"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::POST, "/api/devices/enroll-tokens"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::DELETE, "/api/devices/abc/enrollment"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/invitations"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::DELETE, "/api/invitations/abc"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::POST, "/api/releases"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/releases/latest"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::POST, "/api/relay-nodes/heartbeat"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::GET, "/api/relay-nodes"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/releases/linux/x86_64/1.2.0/binary"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::DELETE, "/api/releases/latest"), RouteClass::SessionControl);
+        assert_eq!(classify_route(&Method::POST, "/api/devices/abc/diagnostics"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::GET, "/api/devices/abc/diagnostics"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::GET, "/api/policies"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::PUT, "/api/policies/abc"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/webhooks"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::POST, "/api/webhooks/abc/test"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::GET, "/api/devices/abc/policy"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::GET, "/api/custom-fields"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::POST, "/api/custom-fields"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::DELETE, "/api/custom-fields/abc"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::PUT, "/api/devices/abc/fields"), RouteClass::SessionControl);
+        assert_eq!(classify_route(&Method::PUT, "/api/devices/abc/notes"), RouteClass::SessionControl);
+        assert_eq!(classify_route(&Method::GET, "/api/note-templates"), RouteClass::Authenticated);
+        assert_eq!(classify_route(&Method::PUT, "/api/note-templates/abc"), RouteClass::Administration);
+        assert_eq!(classify_route(&Method::POST, "/api/sessions/abc/notes"), RouteClass::SessionControl);
+        assert_eq!(classify_route(&Method::GET, "/api/ws"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::GET, "/api/session-tokens/key"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::GET, "/api/auth/guest"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::POST, "/api/devices/abc/grants"), RouteClass::Delegation);
+        assert_eq!(classify_route(&Method::GET, "/api/grants"), RouteClass::Delegation);
+        assert_eq!(classify_route(&Method::DELETE, "/api/grants/abc"), RouteClass::Delegation);
+        assert_eq!(classify_route(&Method::POST, "/api/recordings"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::PUT, "/api/recordings/abc/chunks/3"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::GET, "/api/recordings"), RouteClass::Review);
+        assert_eq!(classify_route(&Method::GET, "/api/recordings/abc/stream"), RouteClass::Review);
+        assert_eq!(classify_route(&Method::DELETE, "/api/recordings/abc"), RouteClass::Review);
+        assert_eq!(classify_route(&Method::POST, "/api/crash-reports"), RouteClass::Public);
+        assert_eq!(classify_route(&Method::GET, "/api/crash-reports/groups"), RouteClass::Authenticated);
+    }
+
+    #[test]
+    fn test_guest_routes() {
+        assert!(check_role(&UserRole::Operator, RouteClass::Delegation).is_ok());
+        assert!(check_role(&UserRole::Technician, RouteClass::Delegation).is_err());
+        assert!(check_role(&UserRole::Operator, RouteClass::Review).is_ok());
+        assert!(check_role(&UserRole::Technician, RouteClass::Review).is_err());
+
+        assert!(check_guest_route(&Method::GET, "/api/devices/abc").is_ok());
+        assert!(check_guest_route(&Method::POST, "/api/devices/abc/sessions").is_ok());
+        assert!(check_guest_route(&Method::POST, "/api/sessions/abc/notes").is_ok());
+        for (method, path) in [
+            (Method::DELETE, "/api/devices/abc"),
+            (Method::GET, "/api/devices/abc/sessions"),
+            (Method::GET, "/api/devices/abc/diagnostics"),
+            (Method::GET, "/api/grants"),
+            (Method::GET, "/api/vpn/peers"),
+            (Method::POST, "/api/auth/switch-organization"),
+            (Method::PUT, "/api/sessions/abc/notes/def"),
+            (Method::PUT, "/api/sessions/abc/ticket"),
+        ] {
+            assert!(check_guest_route(&method, path).is_err(), "{} {}", method, path);
+        }
+    }
+}
@@ -203,6 +203,9 @@ impl DeviceRegistrationService {
             settings: sqlx::types::Json(HashMap::new()),
             created_at: Utc::now(),
             updated_at: Utc::now(),
+            group_id: None,
+            tags: Vec::new(),
+            alias: None,
         };
         
         self.db.create_agent(&agent).await?;
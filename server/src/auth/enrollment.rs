@@ -0,0 +1,901 @@
+//! Agent enrollment
+//!
+//! An admin mints a short-lived enrollment token and hands it to the install
+//! command on the device. The agent generates an Ed25519 keypair, presents the
+//! token together with its public key once, and from then on signs every
+//! relay registration with the private key. A token minted in an
+//! organization enrolls the agent into it. Agents may present the code of an
+//! invitation instead of a token ([`invitations`]). The relay refuses agents it
+//! cannot verify unless `allow_unauthenticated_agents` is set for migrating
+//! older deployments.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::invitations::{self, Invitation, InvitationError, Invitations};
+use crate::auth::jwt::{error, AuthUser};
+use crate::database::DatabaseService;
+use crate::models::AuditLog;
+use crate::recordings::hex_digest;
+use crate::organizations::Tenant;
+use crate::AppState;
+
+/// `purpose` claim that distinguishes enrollment tokens from access tokens
+const ENROLLMENT_PURPOSE: &str = "agent-enrollment";
+
+/// Lifetime of a token when the admin does not ask for one
+const DEFAULT_TOKEN_TTL_MINUTES: i64 = 60;
+
+/// Longest lifetime an admin may request
+const MAX_TOKEN_TTL_MINUTES: i64 = 24 * 60;
+
+/// Prefix of the bytes an agent signs to register; must match the client
+pub const REGISTRATION_CONTEXT: &str = "ghostlink-agent-register-v1";
+
+/// Prefix of the bytes an agent signs to upload a diagnostics bundle; must
+/// match the client
+pub const DIAGNOSTICS_CONTEXT: &str = "ghostlink-agent-diagnostics-v1";
+
+/// Prefix of the bytes an agent signs to upload part of a session
+/// recording; must match the client
+pub const RECORDING_CONTEXT: &str = "ghostlink-agent-recording-v1";
+
+/// Prefix of the bytes an agent signs to upload a crash report; must match
+/// the client
+pub const CRASH_REPORT_CONTEXT: &str = "ghostlink-agent-crash-report-v1";
+
+/// Unix time an agent signed its upload at
+pub const TIMESTAMP_HEADER: &str = "x-ghostlink-timestamp";
+/// Base64 Ed25519 signature over the upload's payload ([`UploadKind`])
+pub const SIGNATURE_HEADER: &str = "x-ghostlink-signature";
+
+/// How far a registration timestamp may be from the relay's clock
+const MAX_REGISTRATION_SKEW_SECS: i64 = 300;
+
+/// WebSocket close code: the first message was not a usable registration
+pub const CLOSE_BAD_REGISTRATION: u16 = 4400;
+/// WebSocket close code: the agent is not enrolled or did not sign
+pub const CLOSE_NOT_ENROLLED: u16 = 4401;
+/// WebSocket close code: the registration signature did not verify
+pub const CLOSE_INVALID_SIGNATURE: u16 = 4403;
+
+/// Bytes an agent signs to register
+pub fn registration_payload(agent_id: &str, timestamp: i64) -> String {
+    format!("{}:{}:{}", REGISTRATION_CONTEXT, agent_id, timestamp)
+}
+
+/// Bytes an agent signs to upload a diagnostics bundle with this SHA-256
+pub fn diagnostics_payload(agent_id: &str, timestamp: i64, sha256_hex: &str) -> String {
+    format!("{}:{}:{}:{}", DIAGNOSTICS_CONTEXT, agent_id, timestamp, sha256_hex)
+}
+
+/// Bytes an agent signs to upload a recording declaration or chunk with
+/// this SHA-256
+pub fn recording_payload(agent_id: &str, timestamp: i64, sha256_hex: &str) -> String {
+    format!("{}:{}:{}:{}", RECORDING_CONTEXT, agent_id, timestamp, sha256_hex)
+}
+
+/// Bytes an agent signs to upload a crash report with this SHA-256
+pub fn crash_report_payload(agent_id: &str, timestamp: i64, sha256_hex: &str) -> String {
+    format!("{}:{}:{}:{}", CRASH_REPORT_CONTEXT, agent_id, timestamp, sha256_hex)
+}
+
+/// What an agent uploads signed with its device key; each is signed in a
+/// context of its own, so one cannot pass for another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadKind {
+    /// Over [`diagnostics_payload`]
+    Diagnostics,
+    /// Over [`recording_payload`]
+    Recording,
+    /// Over [`crash_report_payload`]
+    CrashReport,
+}
+
+impl UploadKind {
+    fn describe(self) -> &'static str {
+        match self {
+            UploadKind::Diagnostics => "diagnostics upload",
+            UploadKind::Recording => "recording upload",
+            UploadKind::CrashReport => "crash report",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EnrollmentClaims {
+    jti: String,
+    purpose: String,
+    /// ID of the admin who minted the token
+    issued_by: String,
+    /// Organization the agent enrolls into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    organization_id: Option<Uuid>,
+    /// Invitation the token came with; enrolling uses it up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invitation_id: Option<Uuid>,
+    iat: i64,
+    exp: i64,
+}
+
+/// Freshly minted enrollment token
+#[derive(Debug, Serialize)]
+pub struct EnrollmentToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnrollmentError {
+    InvalidToken,
+    TokenExpired,
+    InvalidPublicKey,
+    /// The agent sent no signature and unauthenticated agents are not allowed
+    SignatureRequired,
+    /// The agent signed, but never enrolled a key with this server
+    UnknownAgent,
+    InvalidSignature,
+    /// The signed timestamp is too far from the relay's clock
+    StaleTimestamp,
+    /// The agent is already enrolled in another organization
+    OrganizationMismatch,
+    /// The agent already has a key; an admin must reset it before another is enrolled
+    AlreadyEnrolled,
+    /// The invitation code or the one the token came with cannot be used
+    Invitation(InvitationError),
+    Database(String),
+}
+
+impl EnrollmentError {
+    /// Close code the relay sends when rejecting a registration with this error
+    pub fn close_code(&self) -> u16 {
+        match self {
+            EnrollmentError::SignatureRequired | EnrollmentError::UnknownAgent => CLOSE_NOT_ENROLLED,
+            EnrollmentError::InvalidSignature | EnrollmentError::StaleTimestamp => CLOSE_INVALID_SIGNATURE,
+            _ => CLOSE_BAD_REGISTRATION,
+        }
+    }
+}
+
+impl std::fmt::Display for EnrollmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnrollmentError::InvalidToken => write!(f, "invalid enrollment token"),
+            EnrollmentError::TokenExpired => write!(f, "enrollment token expired"),
+            EnrollmentError::InvalidPublicKey => write!(f, "public key must be 32 base64-encoded bytes"),
+            EnrollmentError::SignatureRequired => write!(f, "agent is not enrolled; registration must be signed"),
+            EnrollmentError::UnknownAgent => write!(f, "agent is not enrolled with this server"),
+            EnrollmentError::InvalidSignature => write!(f, "registration signature is invalid"),
+            EnrollmentError::StaleTimestamp => write!(f, "registration timestamp is outside the allowed clock skew"),
+            EnrollmentError::OrganizationMismatch => write!(f, "agent is enrolled in another organization"),
+            EnrollmentError::AlreadyEnrolled => write!(f, "agent is already enrolled; an admin must reset its key first"),
+            EnrollmentError::Invitation(e) => e.fmt(f),
+            EnrollmentError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+/// An agent's enrolled key and the organization it enrolled into
+#[derive(Debug, Clone)]
+struct Credential {
+    public_key: Vec<u8>,
+    organization_id: Option<Uuid>,
+}
+
+/// Mints enrollment tokens and verifies agent registrations
+pub struct EnrollmentService {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    allow_unauthenticated_agents: bool,
+    /// Enrolled Ed25519 public keys, indexed by agent ID
+    credentials: RwLock<HashMap<Uuid, Credential>>,
+    invitations: Invitations,
+    db: Option<Arc<DatabaseService>>,
+}
+
+impl EnrollmentService {
+    pub fn new(secret: &str, allow_unauthenticated_agents: bool) -> Self {
+        Self {
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            allow_unauthenticated_agents,
+            credentials: RwLock::new(HashMap::new()),
+            invitations: Invitations::new(),
+            db: None,
+        }
+    }
+
+    /// Keep enrolled keys in the agents table so they survive restarts
+    pub fn with_database(mut self, db: Arc<DatabaseService>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    pub fn allows_unauthenticated_agents(&self) -> bool {
+        self.allow_unauthenticated_agents
+    }
+
+    pub fn invitations(&self) -> &Invitations {
+        &self.invitations
+    }
+
+    /// Mint a token enrolling agents into `organization_id`, valid for `ttl`
+    /// clamped to at most a day
+    pub fn mint_token(
+        &self,
+        issued_by: Uuid,
+        organization_id: Option<Uuid>,
+        ttl: Duration,
+    ) -> Result<EnrollmentToken, EnrollmentError> {
+        self.mint_token_at(issued_by, organization_id, ttl, Utc::now())
+    }
+
+    fn mint_token_at(
+        &self,
+        issued_by: Uuid,
+        organization_id: Option<Uuid>,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<EnrollmentToken, EnrollmentError> {
+        let expires_at = now + ttl.min(Duration::minutes(MAX_TOKEN_TTL_MINUTES));
+        self.sign_token(issued_by, organization_id, None, now, expires_at)
+    }
+
+    fn sign_token(
+        &self,
+        issued_by: Uuid,
+        organization_id: Option<Uuid>,
+        invitation_id: Option<Uuid>,
+        issued_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<EnrollmentToken, EnrollmentError> {
+        let claims = EnrollmentClaims {
+            jti: Uuid::new_v4().to_string(),
+            purpose: ENROLLMENT_PURPOSE.to_string(),
+            issued_by: issued_by.to_string(),
+            organization_id,
+            invitation_id,
+            iat: issued_at.timestamp(),
+            exp: expires_at.timestamp(),
+        };
+        let token = encode(&Header::default(), &claims, &self.encoding_key)
+            .map_err(|_| EnrollmentError::InvalidToken)?;
+
+        Ok(EnrollmentToken { token, expires_at })
+    }
+
+    /// Create an invitation enrolling up to `max_uses` agents into
+    /// `organization_id` for `ttl`, clamped to at most a day, and the token
+    /// bound to it that goes in its provisioning payload
+    pub async fn create_invitation(
+        &self,
+        issued_by: Uuid,
+        organization_id: Option<Uuid>,
+        ttl: Duration,
+        max_uses: u32,
+    ) -> Result<(Invitation, EnrollmentToken), EnrollmentError> {
+        self.create_invitation_at(issued_by, organization_id, ttl, max_uses, Utc::now()).await
+    }
+
+    async fn create_invitation_at(
+        &self,
+        issued_by: Uuid,
+        organization_id: Option<Uuid>,
+        ttl: Duration,
+        max_uses: u32,
+        now: DateTime<Utc>,
+    ) -> Result<(Invitation, EnrollmentToken), EnrollmentError> {
+        let ttl = ttl.min(Duration::minutes(invitations::MAX_TTL_MINUTES));
+        let invitation = self.invitations.create(issued_by, organization_id, ttl, max_uses, now).await;
+        let token = self.sign_token(issued_by, organization_id, Some(invitation.id), now, invitation.expires_at)?;
+        Ok((invitation, token))
+    }
+
+    fn verify_token(&self, token: &str) -> Result<EnrollmentClaims, EnrollmentError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = decode::<EnrollmentClaims>(token, &self.decoding_key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => EnrollmentError::TokenExpired,
+                _ => EnrollmentError::InvalidToken,
+            })?
+            .claims;
+
+        if claims.purpose != ENROLLMENT_PURPOSE {
+            return Err(EnrollmentError::InvalidToken);
+        }
+        Ok(claims)
+    }
+
+    /// Exchange an enrollment token or invitation code for a stored device
+    /// key, returning the organization the agent enrolled into. An agent that
+    /// already has a key cannot enroll another until an admin resets it.
+    pub async fn enroll(&self, request: &EnrollRequest) -> Result<Option<Uuid>, EnrollmentError> {
+        let (organization_id, invitation_id, presented) = match (&request.token, &request.code) {
+            (Some(token), _) => {
+                let claims = self.verify_token(token)?;
+                let presented = format!("token {} issued by {}", claims.jti, claims.issued_by);
+                (claims.organization_id, claims.invitation_id, presented)
+            }
+            (None, Some(code)) => {
+                let invitation = self.invitations.by_code(code).await.map_err(EnrollmentError::Invitation)?;
+                let presented = format!("invitation {} created by {}", invitation.id, invitation.created_by);
+                (invitation.organization_id, Some(invitation.id), presented)
+            }
+            (None, None) => return Err(EnrollmentError::InvalidToken),
+        };
+        let public_key = BASE64.decode(&request.public_key)
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or(EnrollmentError::InvalidPublicKey)?;
+        if let Some(enrolled) = self.credential(request.agent_id).await? {
+            if enrolled.organization_id != organization_id {
+                return Err(EnrollmentError::OrganizationMismatch);
+            }
+            return Err(EnrollmentError::AlreadyEnrolled);
+        }
+        if let Some(invitation_id) = invitation_id {
+            self.invitations.redeem(invitation_id, request.agent_id, Utc::now()).await
+                .map_err(EnrollmentError::Invitation)?;
+        }
+
+        if let Some(db) = &self.db {
+            let stored = db.enroll_agent(
+                request.agent_id,
+                &request.hostname,
+                &request.platform,
+                &request.public_key,
+                organization_id,
+            )
+            .await
+            .map_err(|e| EnrollmentError::Database(e.to_string()))?;
+            if !stored {
+                return Err(EnrollmentError::AlreadyEnrolled);
+            }
+        }
+        let credential = Credential { public_key, organization_id };
+        self.credentials.write().await.insert(request.agent_id, credential);
+
+        info!("Agent {} ({}) enrolled with {}", request.agent_id, request.hostname, presented);
+        Ok(organization_id)
+    }
+
+    /// Forget an agent's enrolled key so it can enroll again, e.g. after the
+    /// device was reinstalled
+    pub async fn reset(&self, tenant: Tenant, agent_id: Uuid) -> Result<(), EnrollmentError> {
+        let enrolled = self.credential(agent_id).await?
+            .filter(|credential| tenant.includes(credential.organization_id))
+            .ok_or(EnrollmentError::UnknownAgent)?;
+        if let Some(db) = &self.db {
+            db.reset_agent_key(agent_id).await
+                .map_err(|e| EnrollmentError::Database(e.to_string()))?;
+        }
+        self.credentials.write().await.remove(&agent_id);
+
+        info!("Enrolled key of agent {} reset (organization {:?})", agent_id, enrolled.organization_id);
+        Ok(())
+    }
+
+    async fn credential(&self, agent_id: Uuid) -> Result<Option<Credential>, EnrollmentError> {
+        if let Some(credential) = self.credentials.read().await.get(&agent_id) {
+            return Ok(Some(credential.clone()));
+        }
+
+        let Some(db) = &self.db else { return Ok(None) };
+        let stored = db.get_agent_by_id(agent_id).await
+            .map_err(|e| EnrollmentError::Database(e.to_string()))?
+            .and_then(|agent| {
+                let public_key = BASE64.decode(agent.public_key?).ok()?;
+                Some(Credential { public_key, organization_id: agent.organization_id })
+            });
+        if let Some(credential) = &stored {
+            self.credentials.write().await.insert(agent_id, credential.clone());
+        }
+        Ok(stored)
+    }
+
+    /// Check an agent's registration before the relay accepts it, returning
+    /// the organization it enrolled into.
+    ///
+    /// Enrolled agents must always sign. Agents without a key are let in
+    /// unsigned, in no organization, only while `allow_unauthenticated_agents`
+    /// is set.
+    pub async fn verify_registration(
+        &self,
+        agent_id: Uuid,
+        timestamp: i64,
+        signature: Option<&str>,
+    ) -> Result<Option<Uuid>, EnrollmentError> {
+        let credential = self.credential(agent_id).await?;
+
+        let (credential, signature) = match (credential, signature) {
+            (Some(credential), Some(signature)) => (credential, signature),
+            (None, None) if self.allow_unauthenticated_agents => {
+                warn!("Accepting unauthenticated legacy agent {}", agent_id);
+                return Ok(None);
+            }
+            (None, Some(_)) => return Err(EnrollmentError::UnknownAgent),
+            (_, None) => return Err(EnrollmentError::SignatureRequired),
+        };
+
+        let payload = registration_payload(&agent_id.to_string(), timestamp);
+        verify_signed(&credential.public_key, timestamp, &payload, signature)?;
+        Ok(credential.organization_id)
+    }
+
+    /// Check the signature on an agent's diagnostics upload. Only enrolled
+    /// agents can upload, whatever `allow_unauthenticated_agents` says.
+    pub async fn verify_diagnostics(
+        &self,
+        agent_id: Uuid,
+        timestamp: i64,
+        signature: &str,
+        sha256_hex: &str,
+    ) -> Result<(), EnrollmentError> {
+        let credential = self.credential(agent_id).await?.ok_or(EnrollmentError::UnknownAgent)?;
+        let payload = diagnostics_payload(&agent_id.to_string(), timestamp, sha256_hex);
+        verify_signed(&credential.public_key, timestamp, &payload, signature)
+    }
+
+    /// Check the signature on part of an agent's recording upload; like
+    /// diagnostics, only enrolled agents can upload
+    pub async fn verify_recording(
+        &self,
+        agent_id: Uuid,
+        timestamp: i64,
+        signature: &str,
+        sha256_hex: &str,
+    ) -> Result<(), EnrollmentError> {
+        let credential = self.credential(agent_id).await?.ok_or(EnrollmentError::UnknownAgent)?;
+        let payload = recording_payload(&agent_id.to_string(), timestamp, sha256_hex);
+        verify_signed(&credential.public_key, timestamp, &payload, signature)
+    }
+
+    /// Check the signature on an agent's crash report upload; like
+    /// diagnostics, only enrolled agents can upload
+    pub async fn verify_crash_report(
+        &self,
+        agent_id: Uuid,
+        timestamp: i64,
+        signature: &str,
+        sha256_hex: &str,
+    ) -> Result<(), EnrollmentError> {
+        let credential = self.credential(agent_id).await?.ok_or(EnrollmentError::UnknownAgent)?;
+        let payload = crash_report_payload(&agent_id.to_string(), timestamp, sha256_hex);
+        verify_signed(&credential.public_key, timestamp, &payload, signature)
+    }
+
+    /// Check the signature in an upload's headers against `agent_id`'s
+    /// enrolled key, returning the SHA-256 of `body` it covers
+    pub async fn verify_signed_upload(
+        &self,
+        headers: &HeaderMap,
+        agent_id: Uuid,
+        body: &[u8],
+        kind: UploadKind,
+    ) -> Result<String, Response> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let (Some(timestamp), Some(signature)) = (header(TIMESTAMP_HEADER).and_then(|t| t.parse().ok()), header(SIGNATURE_HEADER)) else {
+            return Err(error(StatusCode::UNAUTHORIZED, format!("A {} must be signed with the device key", kind.describe())));
+        };
+
+        let sha256 = hex_digest(body);
+        let verified = match kind {
+            UploadKind::Diagnostics => self.verify_diagnostics(agent_id, timestamp, signature, &sha256).await,
+            UploadKind::Recording => self.verify_recording(agent_id, timestamp, signature, &sha256).await,
+            UploadKind::CrashReport => self.verify_crash_report(agent_id, timestamp, signature, &sha256).await,
+        };
+        if let Err(e) = verified {
+            warn!("Rejected {} from {}: {}", kind.describe(), agent_id, e);
+            let status = match e {
+                EnrollmentError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNAUTHORIZED,
+            };
+            return Err(error(status, e.to_string()));
+        }
+        Ok(sha256)
+    }
+}
+
+/// Check a fresh signature over `payload` by an enrolled key
+fn verify_signed(public_key: &[u8], timestamp: i64, payload: &str, signature: &str) -> Result<(), EnrollmentError> {
+    if (Utc::now().timestamp() - timestamp).abs() > MAX_REGISTRATION_SKEW_SECS {
+        return Err(EnrollmentError::StaleTimestamp);
+    }
+
+    let signature = BASE64.decode(signature).map_err(|_| EnrollmentError::InvalidSignature)?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(payload.as_bytes(), &signature)
+        .map_err(|_| EnrollmentError::InvalidSignature)
+}
+
+/// Request body for minting an enrollment token
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateEnrollTokenRequest {
+    pub ttl_minutes: Option<i64>,
+}
+
+/// Mint an enrollment token for the caller's organization (admin only)
+pub async fn api_create_enroll_token(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    body: Option<Json<CreateEnrollTokenRequest>>,
+) -> Response {
+    let request = body.map(|Json(request)| request).unwrap_or_default();
+    let ttl_minutes = request.ttl_minutes.unwrap_or(DEFAULT_TOKEN_TTL_MINUTES);
+    if ttl_minutes <= 0 {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": "ttl_minutes must be positive"
+        }))).into_response();
+    }
+
+    match app_state.enrollment.mint_token(user.user_id, tenant.organization_id(), Duration::minutes(ttl_minutes)) {
+        Ok(token) => {
+            info!("Enrollment token minted by {} (expires {})", user.email, token.expires_at);
+            Json(token).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+            "error": e.to_string()
+        }))).into_response(),
+    }
+}
+
+/// Reset a device's enrolled key so it can enroll a new one (admin only)
+pub async fn api_reset_enrollment(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let Ok(agent_id) = Uuid::parse_str(&agent_id) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": "Invalid agent ID format"
+        }))).into_response();
+    };
+    match app_state.enrollment.reset(tenant, agent_id).await {
+        Ok(()) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("device", "enrollment_reset")
+                    .actor(user.user_id.to_string())
+                    .request(&context)
+                    .details(serde_json::json!({ "agent_id": agent_id })),
+            ).await;
+            Json(serde_json::json!({
+                "status": "reset",
+                "agent_id": agent_id
+            })).into_response()
+        }
+        Err(e) => {
+            let status = match &e {
+                EnrollmentError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::NOT_FOUND,
+            };
+            (status, Json(serde_json::json!({
+                "error": e.to_string()
+            }))).into_response()
+        }
+    }
+}
+
+/// Request body an agent sends to enroll, with either a token or an
+/// invitation code
+#[derive(Debug, Deserialize)]
+pub struct EnrollRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub code: Option<String>,
+    pub agent_id: Uuid,
+    pub hostname: String,
+    pub platform: String,
+    /// Base64-encoded Ed25519 public key
+    pub public_key: String,
+}
+
+/// Enroll an agent's public key using a token or invitation code from an admin
+pub async fn api_enroll_device(
+    State(app_state): State<AppState>,
+    Json(request): Json<EnrollRequest>,
+) -> Response {
+    match app_state.enrollment.enroll(&request).await {
+        Ok(organization_id) => Json(serde_json::json!({
+            "status": "enrolled",
+            "agent_id": request.agent_id,
+            "organization_id": organization_id
+        })).into_response(),
+        Err(e) => {
+            let status = match &e {
+                EnrollmentError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                EnrollmentError::InvalidPublicKey => StatusCode::BAD_REQUEST,
+                EnrollmentError::OrganizationMismatch | EnrollmentError::AlreadyEnrolled => StatusCode::CONFLICT,
+                EnrollmentError::Invitation(e) => e.status(),
+                _ => StatusCode::UNAUTHORIZED,
+            };
+            warn!("Enrollment of agent {} rejected: {}", request.agent_id, e);
+            (status, Json(serde_json::json!({
+                "error": e.to_string()
+            }))).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn service(allow_unauthenticated_agents: bool) -> EnrollmentService {
+        EnrollmentService::new("test-secret", allow_unauthenticated_agents)
+    }
+
+    fn keypair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn sign(keypair: &Ed25519KeyPair, agent_id: Uuid, timestamp: i64) -> String {
+        BASE64.encode(keypair.sign(registration_payload(&agent_id.to_string(), timestamp).as_bytes()))
+    }
+
+    async fn enroll(service: &EnrollmentService, agent_id: Uuid, keypair: &Ed25519KeyPair) {
+        let token = service.mint_token(Uuid::new_v4(), None, Duration::minutes(5)).unwrap().token;
+        let request = EnrollRequest {
+            token: Some(token),
+            code: None,
+            agent_id,
+            hostname: "desk-01".to_string(),
+            platform: "linux".to_string(),
+            public_key: BASE64.encode(keypair.public_key().as_ref()),
+        };
+        service.enroll(&request).await.unwrap();
+    }
+
+    #[test]
+    fn test_token_minting_and_expiry() {
+        let service = service(false);
+        let admin = Uuid::new_v4();
+
+        let minted = service.mint_token(admin, None, Duration::minutes(30)).unwrap();
+        let claims = service.verify_token(&minted.token).unwrap();
+        assert_eq!(claims.issued_by, admin.to_string());
+        assert_eq!(claims.exp, minted.expires_at.timestamp());
+
+        // Lifetimes are capped at a day
+        let long = service.mint_token(admin, None, Duration::days(30)).unwrap();
+        assert!(long.expires_at <= Utc::now() + Duration::minutes(MAX_TOKEN_TTL_MINUTES));
+
+        let expired = service.mint_token_at(admin, None, Duration::minutes(5), Utc::now() - Duration::hours(1)).unwrap();
+        assert_eq!(service.verify_token(&expired.token).unwrap_err(), EnrollmentError::TokenExpired);
+
+        // Tokens from another server or for another purpose are rejected
+        let foreign = EnrollmentService::new("other-secret", false).mint_token(admin, None, Duration::minutes(5)).unwrap();
+        assert_eq!(service.verify_token(&foreign.token).unwrap_err(), EnrollmentError::InvalidToken);
+        let access = crate::auth::jwt::JwtService::new("test-secret")
+            .generate_access_token(&admin, "admin@example.com", "admin", None, &[])
+            .unwrap();
+        assert_eq!(service.verify_token(&access).unwrap_err(), EnrollmentError::InvalidToken);
+    }
+
+    #[tokio::test]
+    async fn test_enrolled_agent_signature_verification() {
+        let service = service(false);
+        let agent_id = Uuid::new_v4();
+        let enrolled = keypair();
+        enroll(&service, agent_id, &enrolled).await;
+
+        let now = Utc::now().timestamp();
+        let signature = sign(&enrolled, agent_id, now);
+        service.verify_registration(agent_id, now, Some(&signature)).await.unwrap();
+
+        // A signature over a different timestamp, or from another key, does not verify
+        let err = service.verify_registration(agent_id, now + 1, Some(&signature)).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::InvalidSignature);
+        let forged = sign(&keypair(), agent_id, now);
+        let err = service.verify_registration(agent_id, now, Some(&forged)).await.unwrap_err();
+        assert_eq!(err.close_code(), CLOSE_INVALID_SIGNATURE);
+
+        // Replaying an old registration fails
+        let old = now - MAX_REGISTRATION_SKEW_SECS - 1;
+        let err = service.verify_registration(agent_id, old, Some(&sign(&enrolled, agent_id, old))).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::StaleTimestamp);
+    }
+
+    #[tokio::test]
+    async fn test_unenrolled_agents_are_rejected() {
+        let strict = service(false);
+        let agent_id = Uuid::new_v4();
+        let now = Utc::now().timestamp();
+
+        let err = strict.verify_registration(agent_id, now, None).await.unwrap_err();
+        assert_eq!(err.close_code(), CLOSE_NOT_ENROLLED);
+        let signature = sign(&keypair(), agent_id, now);
+        let err = strict.verify_registration(agent_id, now, Some(&signature)).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::UnknownAgent);
+
+        let request = EnrollRequest {
+            token: Some("not-a-token".to_string()),
+            code: None,
+            agent_id,
+            hostname: "desk-01".to_string(),
+            platform: "linux".to_string(),
+            public_key: BASE64.encode([0u8; 32]),
+        };
+        assert_eq!(strict.enroll(&request).await.unwrap_err(), EnrollmentError::InvalidToken);
+
+        // The migration flag lets legacy agents in, but never unsigned enrolled ones
+        let lenient = service(true);
+        lenient.verify_registration(agent_id, now, None).await.unwrap();
+        let keypair = keypair();
+        enroll(&lenient, agent_id, &keypair).await;
+        let err = lenient.verify_registration(agent_id, now, None).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::SignatureRequired);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_uploads_need_the_enrolled_key() {
+        let service = service(true);
+        let agent_id = Uuid::new_v4();
+        let now = Utc::now().timestamp();
+        let sha256 = "ab".repeat(32);
+        let sign_upload = |keypair: &Ed25519KeyPair, sha256: &str| {
+            BASE64.encode(keypair.sign(diagnostics_payload(&agent_id.to_string(), now, sha256).as_bytes()))
+        };
+
+        // Legacy agents may still register, but cannot upload
+        let stranger = keypair();
+        let err = service.verify_diagnostics(agent_id, now, &sign_upload(&stranger, &sha256), &sha256).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::UnknownAgent);
+
+        let enrolled = keypair();
+        enroll(&service, agent_id, &enrolled).await;
+        let signature = sign_upload(&enrolled, &sha256);
+        service.verify_diagnostics(agent_id, now, &signature, &sha256).await.unwrap();
+
+        // The signature covers the bundle, and registrations are not uploads
+        let other = "cd".repeat(32);
+        let err = service.verify_diagnostics(agent_id, now, &signature, &other).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::InvalidSignature);
+        let registration = sign(&enrolled, agent_id, now);
+        let err = service.verify_diagnostics(agent_id, now, &registration, &sha256).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::InvalidSignature);
+    }
+
+    #[tokio::test]
+    async fn test_recording_and_diagnostics_signatures_differ() {
+        let service = service(false);
+        let agent_id = Uuid::new_v4();
+        let now = Utc::now().timestamp();
+        let sha256 = "ab".repeat(32);
+        let keypair = keypair();
+        enroll(&service, agent_id, &keypair).await;
+
+        let recording = BASE64.encode(keypair.sign(recording_payload(&agent_id.to_string(), now, &sha256).as_bytes()));
+        service.verify_recording(agent_id, now, &recording, &sha256).await.unwrap();
+        let err = service.verify_diagnostics(agent_id, now, &recording, &sha256).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::InvalidSignature);
+        let err = service.verify_recording(Uuid::new_v4(), now, &recording, &sha256).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::UnknownAgent);
+
+        let crash = BASE64.encode(keypair.sign(crash_report_payload(&agent_id.to_string(), now, &sha256).as_bytes()));
+        service.verify_crash_report(agent_id, now, &crash, &sha256).await.unwrap();
+        let err = service.verify_crash_report(agent_id, now, &recording, &sha256).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::InvalidSignature);
+    }
+
+    #[tokio::test]
+    async fn test_agents_enroll_into_the_token_organization() {
+        let service = service(false);
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+        let agent_id = Uuid::new_v4();
+        let keypair = keypair();
+        let request = |organization_id| EnrollRequest {
+            token: Some(service.mint_token(Uuid::new_v4(), organization_id, Duration::minutes(5)).unwrap().token),
+            code: None,
+            agent_id,
+            hostname: "till-01".to_string(),
+            platform: "windows".to_string(),
+            public_key: BASE64.encode(keypair.public_key().as_ref()),
+        };
+        service.enroll(&request(Some(acme))).await.unwrap();
+
+        let now = Utc::now().timestamp();
+        let organization = service.verify_registration(agent_id, now, Some(&sign(&keypair, agent_id, now))).await.unwrap();
+        assert_eq!(organization, Some(acme));
+
+        // Another organization's token cannot take the agent over
+        assert_eq!(service.enroll(&request(Some(globex))).await.unwrap_err(), EnrollmentError::OrganizationMismatch);
+        assert_eq!(service.enroll(&request(None)).await.unwrap_err(), EnrollmentError::OrganizationMismatch);
+        assert_eq!(service.enroll(&request(Some(acme))).await.unwrap_err(), EnrollmentError::AlreadyEnrolled);
+    }
+
+    #[tokio::test]
+    async fn test_enrolled_agents_are_not_rekeyed_until_reset() {
+        let service = service(false);
+        let acme = Uuid::new_v4();
+        let agent_id = Uuid::new_v4();
+        let (original, replacement) = (keypair(), keypair());
+        let request = |keypair: &Ed25519KeyPair| EnrollRequest {
+            token: Some(service.mint_token(Uuid::new_v4(), Some(acme), Duration::minutes(5)).unwrap().token),
+            code: None,
+            agent_id,
+            hostname: "till-01".to_string(),
+            platform: "windows".to_string(),
+            public_key: BASE64.encode(keypair.public_key().as_ref()),
+        };
+        service.enroll(&request(&original)).await.unwrap();
+
+        // A second token does not let someone swap in their own key
+        let err = service.enroll(&request(&replacement)).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::AlreadyEnrolled);
+        let now = Utc::now().timestamp();
+        let err = service.verify_registration(agent_id, now, Some(&sign(&replacement, agent_id, now))).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::InvalidSignature);
+
+        // Only an admin of the agent's organization can reset it
+        let globex = Tenant::Organization(Some(Uuid::new_v4()));
+        assert_eq!(service.reset(globex, agent_id).await.unwrap_err(), EnrollmentError::UnknownAgent);
+        service.reset(Tenant::Organization(Some(acme)), agent_id).await.unwrap();
+        assert_eq!(service.reset(Tenant::All, agent_id).await.unwrap_err(), EnrollmentError::UnknownAgent);
+
+        service.enroll(&request(&replacement)).await.unwrap();
+        service.verify_registration(agent_id, now, Some(&sign(&replacement, agent_id, now))).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invitations_enroll_with_their_code_or_token() {
+        let service = service(false);
+        let acme = Uuid::new_v4();
+        let (invitation, token) = service.create_invitation(Uuid::new_v4(), Some(acme), Duration::minutes(15), 2).await.unwrap();
+        let request = |token: Option<String>, code: Option<&str>| EnrollRequest {
+            token,
+            code: code.map(str::to_string),
+            agent_id: Uuid::new_v4(),
+            hostname: "till-01".to_string(),
+            platform: "windows".to_string(),
+            public_key: BASE64.encode(keypair().public_key().as_ref()),
+        };
+
+        let by_code = request(None, Some(&invitation.code.to_lowercase()));
+        assert_eq!(service.enroll(&by_code).await.unwrap(), Some(acme));
+        assert_eq!(service.enroll(&request(Some(token.token), None)).await.unwrap(), Some(acme));
+        let err = service.enroll(&request(None, Some(&invitation.code))).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::Invitation(InvitationError::Exhausted));
+        assert!(err.to_string().contains("as many devices as it allows"));
+        // Enrolling again needs an admin to reset the device first
+        assert_eq!(service.enroll(&by_code).await.unwrap_err(), EnrollmentError::AlreadyEnrolled);
+
+        let (revoked, token) = service.create_invitation(Uuid::new_v4(), Some(acme), Duration::minutes(15), 5).await.unwrap();
+        service.invitations().revoke(Tenant::All, revoked.id, Utc::now()).await.unwrap();
+        let err = service.enroll(&request(Some(token.token), None)).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::Invitation(InvitationError::Revoked));
+
+        let an_hour_ago = Utc::now() - Duration::hours(1);
+        let (expired, token) = service
+            .create_invitation_at(Uuid::new_v4(), None, Duration::minutes(15), 5, an_hour_ago)
+            .await
+            .unwrap();
+        let err = service.enroll(&request(None, Some(&expired.code))).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::Invitation(InvitationError::Expired));
+        assert_eq!(service.enroll(&request(Some(token.token), None)).await.unwrap_err(), EnrollmentError::TokenExpired);
+        let err = service.enroll(&request(None, Some("NOPE2345"))).await.unwrap_err();
+        assert_eq!(err, EnrollmentError::Invitation(InvitationError::Unknown));
+    }
+}
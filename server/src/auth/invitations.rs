@@ -0,0 +1,448 @@
+//! Device invitations
+//!
+//! An admin creates an invitation for their organization: a short code to
+//! type into `ghostlink-client enroll --code`, and a provisioning payload
+//! with the relay URL and an enrollment token bound to the invitation, shown
+//! as a QR code for devices that scan one. Either enrolls a device into the
+//! organization and uses up one of the invitation's `max_uses`. Once those
+//! are used up, the invitation expires or an admin revokes it, both the code
+//! and the token stop working, with an error saying which.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use image::{GrayImage, ImageOutputFormat, Luma};
+use qrcode::{Color, QrCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+use tokio::sync::RwLock;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::jwt::{error, AuthUser};
+use crate::config::AppConfig;
+use crate::models::AuditLog;
+use crate::organizations::Tenant;
+use crate::support_codes::{normalize_code, random_code};
+use crate::AppState;
+
+/// Characters per invitation code
+pub const CODE_LENGTH: usize = 8;
+
+/// Lifetime of an invitation when the admin does not ask for one
+const DEFAULT_TTL_MINUTES: i64 = 60;
+
+/// Longest lifetime an admin may request; the enrollment token in the
+/// provisioning payload lives no longer
+pub const MAX_TTL_MINUTES: i64 = 24 * 60;
+
+/// Devices an invitation enrolls when the admin does not ask for more
+const DEFAULT_MAX_USES: u32 = 1;
+
+/// Most devices one invitation may enroll
+const MAX_USES: u32 = 1000;
+
+/// How long an ended invitation is kept, so devices presenting it late are
+/// told why it stopped working instead of that it never existed
+const RETENTION_HOURS: i64 = 24;
+
+/// Pixels per QR code module
+const QR_MODULE_PIXELS: u32 = 8;
+
+/// Light modules around the QR code, as scanners expect
+const QR_QUIET_ZONE: u32 = 4;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Invitation {
+    pub id: Uuid,
+    pub code: String,
+    /// Organization devices enrolled with it join
+    pub organization_id: Option<Uuid>,
+    /// Admin who created it
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub max_uses: u32,
+    /// Devices enrolled with it, first enrollment first
+    pub enrolled_agents: Vec<Uuid>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Invitation {
+    /// Why the invitation can no longer enroll a device, if it cannot
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), InvitationError> {
+        if self.revoked_at.is_some() {
+            Err(InvitationError::Revoked)
+        } else if now >= self.expires_at {
+            Err(InvitationError::Expired)
+        } else if self.enrolled_agents.len() >= self.max_uses as usize {
+            Err(InvitationError::Exhausted)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvitationError {
+    Unknown,
+    Expired,
+    /// As many devices enrolled with it as it allows
+    Exhausted,
+    Revoked,
+}
+
+impl InvitationError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            InvitationError::Unknown => StatusCode::NOT_FOUND,
+            InvitationError::Expired | InvitationError::Exhausted | InvitationError::Revoked => StatusCode::GONE,
+        }
+    }
+}
+
+impl std::fmt::Display for InvitationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvitationError::Unknown => write!(f, "unknown invitation code"),
+            InvitationError::Expired => write!(f, "invitation code expired; ask your administrator for a new one"),
+            InvitationError::Exhausted => write!(f, "invitation code was already used by as many devices as it allows"),
+            InvitationError::Revoked => write!(f, "invitation code was revoked"),
+        }
+    }
+}
+
+impl IntoResponse for InvitationError {
+    fn into_response(self) -> Response {
+        error(self.status(), self.to_string())
+    }
+}
+
+/// Invitations that are live or ended recently
+#[derive(Debug, Default)]
+pub struct Invitations {
+    invitations: RwLock<HashMap<Uuid, Invitation>>,
+}
+
+impl Invitations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an invitation with a code unique among the kept ones
+    pub async fn create(
+        &self,
+        created_by: Uuid,
+        organization_id: Option<Uuid>,
+        ttl: Duration,
+        max_uses: u32,
+        now: DateTime<Utc>,
+    ) -> Invitation {
+        let mut invitations = self.invitations.write().await;
+        invitations.retain(|_, invitation| invitation.expires_at + Duration::hours(RETENTION_HOURS) > now);
+        let code = loop {
+            let code = random_code(CODE_LENGTH);
+            if !invitations.values().any(|invitation| invitation.code == code) {
+                break code;
+            }
+        };
+        let invitation = Invitation {
+            id: Uuid::new_v4(),
+            code,
+            organization_id,
+            created_by,
+            created_at: now,
+            expires_at: now + ttl,
+            max_uses,
+            enrolled_agents: Vec::new(),
+            revoked_at: None,
+        };
+        invitations.insert(invitation.id, invitation.clone());
+        invitation
+    }
+
+    /// Invitations of `tenant` that can still enroll a device, oldest first
+    pub async fn active(&self, tenant: Tenant, now: DateTime<Utc>) -> Vec<Invitation> {
+        let mut active: Vec<Invitation> = self
+            .invitations
+            .read()
+            .await
+            .values()
+            .filter(|invitation| tenant.includes(invitation.organization_id) && invitation.check(now).is_ok())
+            .cloned()
+            .collect();
+        active.sort_by_key(|invitation| invitation.created_at);
+        active
+    }
+
+    /// The invitation with `code`, whether or not it can still be used
+    pub async fn by_code(&self, code: &str) -> Result<Invitation, InvitationError> {
+        let code = normalize_code(code);
+        self.invitations
+            .read()
+            .await
+            .values()
+            .find(|invitation| invitation.code == code)
+            .cloned()
+            .ok_or(InvitationError::Unknown)
+    }
+
+    /// Use up one enrollment of invitation `id` for `agent_id`. A device
+    /// enrolling again with the same invitation, say after a lost response,
+    /// uses none.
+    pub async fn redeem(&self, id: Uuid, agent_id: Uuid, now: DateTime<Utc>) -> Result<Invitation, InvitationError> {
+        let mut invitations = self.invitations.write().await;
+        let invitation = invitations.get_mut(&id).ok_or(InvitationError::Unknown)?;
+        let enrolled_before = invitation.enrolled_agents.contains(&agent_id);
+        match invitation.check(now) {
+            Ok(()) => {}
+            Err(InvitationError::Exhausted) if enrolled_before => {}
+            Err(e) => return Err(e),
+        }
+        if !enrolled_before {
+            invitation.enrolled_agents.push(agent_id);
+        }
+        Ok(invitation.clone())
+    }
+
+    /// Revoke invitation `id` of `tenant`; revoking it again changes nothing
+    pub async fn revoke(&self, tenant: Tenant, id: Uuid, now: DateTime<Utc>) -> Result<Invitation, InvitationError> {
+        let mut invitations = self.invitations.write().await;
+        let invitation = invitations
+            .get_mut(&id)
+            .filter(|invitation| tenant.includes(invitation.organization_id))
+            .ok_or(InvitationError::Unknown)?;
+        invitation.revoked_at.get_or_insert(now);
+        Ok(invitation.clone())
+    }
+}
+
+/// `data` as a QR code PNG, in a `data:` URL the web shows as is
+pub fn qr_code_data_url(data: &str) -> Result<String, String> {
+    let code = QrCode::new(data.as_bytes()).map_err(|e| format!("Failed to encode QR code: {}", e))?;
+    let modules = code.width() as u32;
+    let colors = code.to_colors();
+    let code_area = QR_QUIET_ZONE..QR_QUIET_ZONE + modules;
+    let size = (modules + 2 * QR_QUIET_ZONE) * QR_MODULE_PIXELS;
+    let image = GrayImage::from_fn(size, size, |x, y| {
+        let (column, row) = (x / QR_MODULE_PIXELS, y / QR_MODULE_PIXELS);
+        let dark = code_area.contains(&column)
+            && code_area.contains(&row)
+            && colors[((row - QR_QUIET_ZONE) * modules + column - QR_QUIET_ZONE) as usize] == Color::Dark;
+        Luma([if dark { 0 } else { 255 }])
+    });
+
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .map_err(|e| format!("Failed to write QR code: {}", e))?;
+    Ok(format!("data:image/png;base64,{}", BASE64.encode(png.into_inner())))
+}
+
+/// Relay URL devices are provisioned with: `relay_url` from the config, or
+/// the relay on the host the admin reached the server through
+fn provisioning_server_url(config: &AppConfig, headers: &HeaderMap) -> String {
+    if let Some(relay_url) = &config.relay_url {
+        return relay_url.clone();
+    }
+    let value_of = |name: header::HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+    let host = value_of(header::HOST).unwrap_or("localhost");
+    let secure = value_of(header::HeaderName::from_static("x-forwarded-proto"))
+        .is_some_and(|proto| proto.eq_ignore_ascii_case("https"));
+    format!("{}://{}/relay/ws", if secure { "wss" } else { "ws" }, host)
+}
+
+/// Request body for creating an invitation
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateInvitationRequest {
+    pub ttl_minutes: Option<i64>,
+    pub max_uses: Option<u32>,
+}
+
+/// What a device needs to enroll; the QR code holds it as JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisioningPayload {
+    pub server_url: String,
+    pub enrollment_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedInvitation {
+    #[serde(flatten)]
+    pub invitation: Invitation,
+    pub provisioning: ProvisioningPayload,
+    /// The provisioning payload as a QR code PNG `data:` URL
+    pub qr_code: String,
+}
+
+/// Create an invitation for the caller's organization (admin only)
+pub async fn api_create_invitation(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    headers: HeaderMap,
+    body: Option<Json<CreateInvitationRequest>>,
+) -> Response {
+    let request = body.map(|Json(request)| request).unwrap_or_default();
+    let ttl_minutes = request.ttl_minutes.unwrap_or(DEFAULT_TTL_MINUTES);
+    if !(1..=MAX_TTL_MINUTES).contains(&ttl_minutes) {
+        return error(StatusCode::BAD_REQUEST, format!("ttl_minutes must be between 1 and {}", MAX_TTL_MINUTES));
+    }
+    let max_uses = request.max_uses.unwrap_or(DEFAULT_MAX_USES);
+    if !(1..=MAX_USES).contains(&max_uses) {
+        return error(StatusCode::BAD_REQUEST, format!("max_uses must be between 1 and {}", MAX_USES));
+    }
+
+    let created = app_state
+        .enrollment
+        .create_invitation(user.user_id, tenant.organization_id(), Duration::minutes(ttl_minutes), max_uses)
+        .await;
+    let (invitation, token) = match created {
+        Ok(created) => created,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let provisioning = ProvisioningPayload {
+        server_url: provisioning_server_url(&app_state.config, &headers),
+        enrollment_token: token.token,
+    };
+    let qr_code = match serde_json::to_string(&provisioning)
+        .map_err(|e| e.to_string())
+        .and_then(|payload| qr_code_data_url(&payload))
+    {
+        Ok(qr_code) => qr_code,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+
+    app_state.device_manager.record_audit(
+        AuditLog::new("device", "invitation_created")
+            .actor(user.user_id.to_string())
+            .request(&context)
+            .details(serde_json::json!({
+                "invitation_id": invitation.id,
+                "organization_id": invitation.organization_id,
+                "expires_at": invitation.expires_at,
+                "max_uses": invitation.max_uses,
+            })),
+    ).await;
+    info!("Invitation {} created by {} for {} devices", invitation.id, user.email, max_uses);
+    (StatusCode::CREATED, Json(CreatedInvitation { invitation, provisioning, qr_code })).into_response()
+}
+
+/// Invitations of the caller's organization that can still enroll a device
+pub async fn api_list_invitations(State(app_state): State<AppState>, tenant: Tenant) -> Response {
+    let invitations = app_state.enrollment.invitations().active(tenant, Utc::now()).await;
+    Json(serde_json::json!({ "invitations": invitations })).into_response()
+}
+
+/// Revoke an invitation; devices already enrolled with it stay enrolled
+pub async fn api_revoke_invitation(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Path(invitation_id): Path<String>,
+) -> Response {
+    let Ok(invitation_id) = Uuid::parse_str(&invitation_id) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid invitation ID format");
+    };
+    match app_state.enrollment.invitations().revoke(tenant, invitation_id, Utc::now()).await {
+        Ok(invitation) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("device", "invitation_revoked")
+                    .actor(user.user_id.to_string())
+                    .request(&context)
+                    .details(serde_json::json!({ "invitation_id": invitation.id })),
+            ).await;
+            Json(invitation).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_invitations_expire() {
+        let invitations = Invitations::new();
+        let now = Utc::now();
+        let invitation = invitations.create(Uuid::new_v4(), None, Duration::minutes(15), 1, now).await;
+        assert_eq!(invitation.code.len(), CODE_LENGTH);
+
+        let typed = format!("{}-{}", &invitation.code[..4], invitation.code[4..].to_lowercase());
+        assert_eq!(invitations.by_code(&typed).await.unwrap().id, invitation.id);
+        assert_eq!(invitations.by_code("NOPE2345").await.unwrap_err(), InvitationError::Unknown);
+        let later = now + Duration::minutes(15);
+        assert_eq!(invitation.check(later).unwrap_err(), InvitationError::Expired);
+        assert_eq!(invitations.redeem(invitation.id, Uuid::new_v4(), later).await.unwrap_err(), InvitationError::Expired);
+        assert!(invitations.active(Tenant::All, later).await.is_empty());
+
+        // Ended invitations are forgotten once they are a day old
+        let much_later = later + Duration::hours(RETENTION_HOURS);
+        invitations.create(Uuid::new_v4(), None, Duration::minutes(15), 1, much_later).await;
+        assert_eq!(invitations.by_code(&invitation.code).await.unwrap_err(), InvitationError::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_invitations_enroll_at_most_max_uses_devices() {
+        let invitations = Invitations::new();
+        let now = Utc::now();
+        let invitation = invitations.create(Uuid::new_v4(), None, Duration::minutes(15), 2, now).await;
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        invitations.redeem(invitation.id, first, now).await.unwrap();
+        // Enrolling the same device again uses nothing up
+        invitations.redeem(invitation.id, first, now).await.unwrap();
+        let redeemed = invitations.redeem(invitation.id, second, now).await.unwrap();
+        assert_eq!(redeemed.enrolled_agents, [first, second]);
+
+        let err = invitations.redeem(invitation.id, Uuid::new_v4(), now).await.unwrap_err();
+        assert_eq!(err, InvitationError::Exhausted);
+        assert_eq!(err.status(), StatusCode::GONE);
+        assert!(invitations.active(Tenant::All, now).await.is_empty());
+        // ...even once every use is gone
+        invitations.redeem(invitation.id, second, now).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_revoked_invitations_stop_working_in_their_organization_only() {
+        let invitations = Invitations::new();
+        let now = Utc::now();
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+        let own = invitations.create(Uuid::new_v4(), Some(acme), Duration::minutes(15), 5, now).await;
+        let other = invitations.create(Uuid::new_v4(), Some(globex), Duration::minutes(15), 5, now).await;
+
+        let in_acme = Tenant::Organization(Some(acme));
+        let active: Vec<Uuid> = invitations.active(in_acme, now).await.iter().map(|invitation| invitation.id).collect();
+        assert_eq!(active, [own.id]);
+        assert_eq!(invitations.revoke(in_acme, other.id, now).await.unwrap_err(), InvitationError::Unknown);
+
+        let revoked = invitations.revoke(in_acme, own.id, now).await.unwrap();
+        assert_eq!(revoked.revoked_at, Some(now));
+        assert_eq!(invitations.redeem(own.id, Uuid::new_v4(), now).await.unwrap_err(), InvitationError::Revoked);
+        assert!(invitations.active(in_acme, now).await.is_empty());
+        assert_eq!(invitations.active(Tenant::All, now).await.len(), 1);
+    }
+
+    #[test]
+    fn test_qr_code_is_a_png_data_url() {
+        let url = qr_code_data_url(r#"{"server_url":"wss://relay.example.com/relay/ws"}"#).unwrap();
+        let png = BASE64.decode(url.strip_prefix("data:image/png;base64,").unwrap()).unwrap();
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+        assert_eq!(image.width(), image.height());
+        assert_eq!(image.width() % QR_MODULE_PIXELS, 0);
+        // Quiet zone, then the dark corner of a finder pattern
+        let edge = QR_QUIET_ZONE * QR_MODULE_PIXELS;
+        assert_eq!(image.get_pixel(edge - 1, edge - 1).0, [255]);
+        assert_eq!(image.get_pixel(edge, edge).0, [0]);
+    }
+}
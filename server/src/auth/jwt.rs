@@ -1,5 +1,5 @@
 use anyhow::Result;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -21,6 +21,9 @@ pub struct Claims {
     pub nbf: i64,          // Not before
     pub jti: String,       // JWT ID (for revocation)
     pub org_id: Option<String>, // Organization ID
+    /// Organizations the user can switch between, when there is more than one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub orgs: Vec<String>,
 }
 
 /// JWT token response
@@ -71,6 +74,7 @@ impl JwtService {
         email: &str,
         role: &str,
         org_id: Option<&str>,
+        orgs: &[String],
     ) -> Result<String> {
         let now = Utc::now();
         let exp = now + self.access_token_duration;
@@ -84,14 +88,16 @@ impl JwtService {
             nbf: now.timestamp(),
             jti: Uuid::new_v4().to_string(),
             org_id: org_id.map(|s| s.to_string()),
+            orgs: if orgs.len() > 1 { orgs.to_vec() } else { Vec::new() },
         };
 
         let token = encode(&Header::default(), &claims, &self.encoding_key)?;
         Ok(token)
     }
 
-    /// Generate refresh token
-    pub fn generate_refresh_token(&self, user_id: &Uuid) -> Result<String> {
+    /// Generate refresh token; it remembers the organization the user
+    /// switched to
+    pub fn generate_refresh_token(&self, user_id: &Uuid, org_id: Option<&str>) -> Result<String> {
         let now = Utc::now();
         let exp = now + self.refresh_token_duration;
         
@@ -103,7 +109,34 @@ impl JwtService {
             iat: now.timestamp(),
             nbf: now.timestamp(),
             jti: Uuid::new_v4().to_string(),
-            org_id: None,
+            org_id: org_id.map(|s| s.to_string()),
+            orgs: Vec::new(),
+        };
+
+        let token = encode(&Header::default(), &claims, &self.encoding_key)?;
+        Ok(token)
+    }
+
+    /// Generate the access token of the guest of an access grant; the
+    /// grant's ID is the guest's user ID, and the token expires with it
+    pub fn generate_guest_token(
+        &self,
+        grant_id: &Uuid,
+        email: &str,
+        org_id: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<String> {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: grant_id.to_string(),
+            email: email.to_string(),
+            role: crate::models::UserRole::Guest.as_str().to_string(),
+            exp: expires_at.min(now + self.access_token_duration).timestamp(),
+            iat: now.timestamp(),
+            nbf: now.timestamp(),
+            jti: Uuid::new_v4().to_string(),
+            org_id: org_id.map(|s| s.to_string()),
+            orgs: Vec::new(),
         };
 
         let token = encode(&Header::default(), &claims, &self.encoding_key)?;
@@ -124,9 +157,10 @@ impl JwtService {
         email: &str,
         role: &str,
         org_id: Option<&str>,
+        orgs: &[String],
     ) -> Result<TokenResponse> {
-        let access_token = self.generate_access_token(user_id, email, role, org_id)?;
-        let refresh_token = self.generate_refresh_token(user_id)?;
+        let access_token = self.generate_access_token(user_id, email, role, org_id, orgs)?;
+        let refresh_token = self.generate_refresh_token(user_id, org_id)?;
         
         Ok(TokenResponse {
             access_token,
@@ -154,12 +188,21 @@ impl JwtService {
         let user = db.get_user_by_id(user_id).await?
             .ok_or_else(|| anyhow::anyhow!("User not found"))?;
         
-        // Generate new token pair
+        // Generate new token pair, staying in the organization the user
+        // switched to while they still belong to it
+        let orgs = db.get_user_organizations(user.id).await?;
+        let org_id = claims.org_id
+            .and_then(|org_id| Uuid::parse_str(&org_id).ok())
+            .filter(|org_id| orgs.contains(org_id) || (orgs.is_empty() && user.role == "admin"))
+            .or_else(|| orgs.first().copied())
+            .map(|id| id.to_string());
+        let orgs: Vec<String> = orgs.iter().map(Uuid::to_string).collect();
         self.generate_token_pair(
             &user.id,
             &user.email.unwrap_or_default(),
             &user.role,
-            None, // TODO: Get org_id from user
+            org_id.as_deref(),
+            &orgs,
         )
     }
 }
@@ -170,6 +213,8 @@ pub struct AuthUser {
     pub email: String,
     pub role: String,
     pub org_id: Option<String>,
+    /// Organizations the user can switch between, when there is more than one
+    pub orgs: Vec<String>,
 }
 
 #[axum::async_trait]
@@ -180,20 +225,16 @@ where
     type Rejection = AuthError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // Extract Authorization header
-        let auth_header = parts
-            .headers
-            .get("Authorization")
-            .and_then(|h| h.to_str().ok())
-            .ok_or(AuthError::MissingToken)?;
-
-        // Check Bearer prefix
-        if !auth_header.starts_with("Bearer ") {
-            return Err(AuthError::InvalidToken);
-        }
+        let token = match parts.headers.get("Authorization").and_then(|h| h.to_str().ok()) {
+            // Check Bearer prefix
+            Some(auth_header) => auth_header
+                .strip_prefix("Bearer ")
+                .ok_or(AuthError::InvalidToken)?,
+            // Browsers cannot set headers on a WebSocket, so upgrades may
+            // carry the token in the query instead
+            None => websocket_query_token(parts).ok_or(AuthError::MissingToken)?,
+        };
 
-        let token = &auth_header[7..]; // Skip "Bearer "
-        
         // Get JWT secret from environment or config
         let jwt_secret = std::env::var("JWT_SECRET")
             .unwrap_or_else(|_| "your-secret-key".to_string());
@@ -220,10 +261,23 @@ where
             email: claims.email,
             role: claims.role,
             org_id: claims.org_id,
+            orgs: claims.orgs,
         })
     }
 }
 
+/// `access_token` query parameter of a WebSocket upgrade request
+fn websocket_query_token(parts: &Parts) -> Option<&str> {
+    let upgrade = parts.headers.get(axum::http::header::UPGRADE)?;
+    if parts.method != axum::http::Method::GET || !upgrade.as_bytes().eq_ignore_ascii_case(b"websocket") {
+        return None;
+    }
+    parts.uri.query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("access_token="))
+        .filter(|token| !token.is_empty())
+}
+
 /// Authentication error types
 #[derive(Debug)]
 pub enum AuthError {
@@ -232,23 +286,34 @@ pub enum AuthError {
     TokenExpired,
     #[allow(dead_code)]
     Unauthorized,
+    /// Authenticated, but the role or grant does not permit the action
+    Forbidden(String),
+    /// Too many attempts from the address or for the account
+    Throttled(crate::rate_limit::Throttled),
+    /// The record does not exist, or is in another organization
+    NotFound(String),
 }
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
         let (status, message) = match self {
-            AuthError::MissingToken => (StatusCode::UNAUTHORIZED, "Missing authentication token"),
-            AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid authentication token"),
-            AuthError::TokenExpired => (StatusCode::UNAUTHORIZED, "Authentication token expired"),
-            AuthError::Unauthorized => (StatusCode::FORBIDDEN, "Unauthorized access"),
+            AuthError::MissingToken => (StatusCode::UNAUTHORIZED, "Missing authentication token".to_string()),
+            AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid authentication token".to_string()),
+            AuthError::TokenExpired => (StatusCode::UNAUTHORIZED, "Authentication token expired".to_string()),
+            AuthError::Unauthorized => (StatusCode::FORBIDDEN, "Unauthorized access".to_string()),
+            AuthError::Forbidden(reason) => (StatusCode::FORBIDDEN, reason),
+            AuthError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            AuthError::Throttled(throttled) => return throttled.into_response(),
         };
-        
-        (status, Json(serde_json::json!({
-            "error": message
-        }))).into_response()
+        error(status, message)
     }
 }
 
+/// JSON error body every handler answers with
+pub(crate) fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(serde_json::json!({ "error": message.into() }))).into_response()
+}
+
 /// Role-based access control guard
 #[allow(dead_code)]
 pub fn require_role(user_role: &str, required_roles: &[&str]) -> Result<(), AuthError> {
@@ -264,12 +329,35 @@ pub mod endpoints {
     use super::*;
     use argon2::{Argon2, PasswordHash, PasswordVerifier};
     use axum::extract::State;
+    use crate::audit::RequestContext;
+    use crate::models::{AuditLog, User};
     
-    /// Login endpoint
+    /// Login endpoint; every attempt lands in the audit trail
     pub async fn login(
         State(app_state): State<crate::AppState>,
+        context: RequestContext,
         Json(request): Json<LoginRequest>,
     ) -> Result<Json<TokenResponse>, AuthError> {
+        let result = authenticate(&app_state, &request).await;
+        let action = match &result {
+            Ok(_) => "login_succeeded",
+            Err(AuthError::Throttled(_)) => "login_throttled",
+            Err(_) => "login_failed",
+        };
+        app_state.device_manager.record_audit(
+            AuditLog::new("auth", action)
+                .actor(request.username.clone())
+                .request(&context),
+        ).await;
+        result.map(Json)
+    }
+
+    /// Check the credentials, counting failures towards a lockout of the
+    /// account whether or not it exists
+    async fn authenticate(app_state: &crate::AppState, request: &LoginRequest) -> Result<TokenResponse, AuthError> {
+        let limiter = &app_state.device_manager.rate_limiter;
+        limiter.check_login(&request.username).map_err(AuthError::Throttled)?;
+
         // Get database service (required for login)
         let db = app_state.db.as_ref()
             .ok_or(AuthError::InvalidToken)?;
@@ -278,36 +366,53 @@ pub mod endpoints {
         let user = db
             .get_user_by_username(&request.username)
             .await
-            .map_err(|_| AuthError::InvalidToken)?
-            .ok_or(AuthError::InvalidToken)?;
-        
-        // Verify password
-        if let Some(password_hash) = &user.password_hash {
-            let parsed_hash = PasswordHash::new(password_hash)
-                .map_err(|_| AuthError::InvalidToken)?;
-            
-            Argon2::default()
-                .verify_password(request.password.as_bytes(), &parsed_hash)
-                .map_err(|_| AuthError::InvalidToken)?;
-        } else {
-            return Err(AuthError::InvalidToken);
+            .map_err(|_| AuthError::InvalidToken)?;
+        if let Some(user) = &user {
+            // Lockouts recorded before a restart still hold
+            limiter.check_locked_until(user.locked_until).map_err(AuthError::Throttled)?;
         }
+
+        let user = match user {
+            Some(user) if password_matches(&user, &request.password) => user,
+            user => {
+                let stored_failures = user.as_ref().map_or(0, |user| user.failed_login_attempts.max(0) as u32);
+                let failure = limiter.login_failed(&request.username, stored_failures);
+                if let Some(user) = user {
+                    if let Err(e) = db.record_failed_login(user.id, failure.failures as i32, failure.locked_until).await {
+                        tracing::warn!("Failed to record failed login of {}: {}", user.username, e);
+                    }
+                }
+                return Err(AuthError::InvalidToken);
+            }
+        };
+        limiter.login_succeeded(&request.username);
         
-        // Update last login
+        // Update last login, clearing failed attempts
         let _ = db.update_user_last_login(user.id).await;
         
-        // Generate tokens
+        // Generate tokens for the user's first organization; the others
+        // are a switch away
+        let orgs: Vec<String> = db.get_user_organizations(user.id).await
+            .map_err(|_| AuthError::InvalidToken)?
+            .iter()
+            .map(Uuid::to_string)
+            .collect();
         let jwt_service = JwtService::new(&app_state.config.jwt_secret);
-        let tokens = jwt_service
+        jwt_service
             .generate_token_pair(
                 &user.id,
                 &user.email.unwrap_or_default(),
                 &user.role,
-                None,
+                orgs.first().map(String::as_str),
+                &orgs,
             )
-            .map_err(|_| AuthError::InvalidToken)?;
-        
-        Ok(Json(tokens))
+            .map_err(|_| AuthError::InvalidToken)
+    }
+    
+    fn password_matches(user: &User, password: &str) -> bool {
+        user.password_hash.as_deref()
+            .and_then(|hash| PasswordHash::new(hash).ok())
+            .is_some_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
     }
     
     /// Refresh token endpoint
@@ -343,13 +448,14 @@ pub mod endpoints {
     
     /// Get current user info
     pub async fn me(
-        AuthUser { user_id, email, role, org_id }: AuthUser,
+        AuthUser { user_id, email, role, org_id, orgs }: AuthUser,
     ) -> impl IntoResponse {
         Json(serde_json::json!({
             "user_id": user_id,
             "email": email,
             "role": role,
-            "organization_id": org_id
+            "organization_id": org_id,
+            "organizations": orgs
         }))
     }
 }
\ No newline at end of file
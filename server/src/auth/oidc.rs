@@ -12,6 +12,10 @@ use uuid::Uuid;
 use tracing::{info, debug};
 use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
 
+use crate::audit::RequestContext;
+use crate::auth::jwt::{AuthUser, JwtService};
+use crate::database::DatabaseService;
+use crate::models::{self, AuditLog, User};
 use crate::AppState;
 
 /// OIDC authentication manager for Microsoft Entra ID integration
@@ -68,8 +72,46 @@ pub struct RoleMapping {
     pub admin_groups: Vec<String>,
     pub user_groups: Vec<String>,
     pub readonly_groups: Vec<String>,
+    /// Role of users no mapping grants one; `Denied` refuses them
     pub default_role: UserRole,
     pub group_prefix: Option<String>,
+    /// Claim-based grants, applied on every login
+    #[serde(default)]
+    pub mappings: Vec<ClaimMapping>,
+}
+
+/// Grants for users whose `claim` is or contains `value`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClaimMapping {
+    #[serde(default = "default_mapping_claim")]
+    pub claim: String,
+    pub value: String,
+    /// The most privileged role of all matching mappings wins
+    #[serde(default)]
+    pub role: Option<models::UserRole>,
+    /// Device groups whose devices the user may reach
+    #[serde(default)]
+    pub device_groups: Vec<Uuid>,
+    /// Organization the user belongs to; the first matching mapping that
+    /// sets one wins
+    #[serde(default)]
+    pub organization_id: Option<Uuid>,
+    /// Lets the user approve held backstage sessions
+    #[serde(default)]
+    pub approver: bool,
+}
+
+fn default_mapping_claim() -> String {
+    "groups".to_string()
+}
+
+/// What the role mapping grants a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub role: models::UserRole,
+    pub device_groups: Vec<Uuid>,
+    pub organization_id: Option<Uuid>,
+    pub approver: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -80,6 +122,92 @@ pub enum UserRole {
     Denied,
 }
 
+impl UserRole {
+    /// The user role this grants, or `None` for `Denied`
+    pub fn user_role(&self) -> Option<models::UserRole> {
+        match self {
+            UserRole::Admin => Some(models::UserRole::Admin),
+            UserRole::User => Some(models::UserRole::User),
+            UserRole::ReadOnly => Some(models::UserRole::Viewer),
+            UserRole::Denied => None,
+        }
+    }
+}
+
+impl RoleMapping {
+    /// What a user with `claims` is granted, or `None` if nothing grants a
+    /// role and the default role denies access. The legacy group lists are
+    /// matched against `groups_claim`.
+    pub fn resolve(&self, claims: &serde_json::Value, groups_claim: &str) -> Option<Grant> {
+        let groups = claim_values(claims, groups_claim);
+        let in_any = |names: &[String]| names.iter().any(|name| groups.iter().any(|group| group.eq_ignore_ascii_case(name)));
+
+        let mut roles = Vec::new();
+        if in_any(&self.admin_groups) {
+            roles.push(models::UserRole::Admin);
+        }
+        if in_any(&self.user_groups) {
+            roles.push(models::UserRole::User);
+        }
+        if in_any(&self.readonly_groups) {
+            roles.push(models::UserRole::Viewer);
+        }
+
+        let mut device_groups = Vec::new();
+        let mut organization_id = None;
+        let mut approver = false;
+        for mapping in &self.mappings {
+            let values = claim_values(claims, &mapping.claim);
+            if !values.iter().any(|value| value.eq_ignore_ascii_case(&mapping.value)) {
+                continue;
+            }
+            roles.extend(mapping.role.clone());
+            for group in &mapping.device_groups {
+                if !device_groups.contains(group) {
+                    device_groups.push(*group);
+                }
+            }
+            organization_id = organization_id.or(mapping.organization_id);
+            approver |= mapping.approver;
+        }
+
+        let role = roles.into_iter()
+            .min_by_key(privilege_rank)
+            .or_else(|| self.default_role.user_role())?;
+        Some(Grant { role, device_groups, organization_id, approver })
+    }
+}
+
+/// Lower is more privileged
+fn privilege_rank(role: &models::UserRole) -> u8 {
+    match role {
+        models::UserRole::Admin => 0,
+        models::UserRole::Operator => 1,
+        models::UserRole::Technician => 2,
+        models::UserRole::User => 3,
+        models::UserRole::Viewer => 4,
+        models::UserRole::Guest => 5,
+    }
+}
+
+/// Values of a claim that is a string, a number or a list of them
+fn claim_values(claims: &serde_json::Value, name: &str) -> Vec<String> {
+    fn text(value: &serde_json::Value) -> Option<String> {
+        match value {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            serde_json::Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+
+    match claims.get(name) {
+        Some(serde_json::Value::Array(values)) => values.iter().filter_map(text).collect(),
+        Some(value) => text(value).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionConfig {
     pub session_timeout_minutes: u32,
@@ -118,7 +246,8 @@ pub struct UserSession {
     pub given_name: Option<String>,
     pub family_name: Option<String>,
     pub groups: Vec<String>,
-    pub roles: Vec<UserRole>,
+    pub role: models::UserRole,
+    pub organization_id: Option<Uuid>,
     pub department: Option<String>,
     pub company: Option<String>,
     pub created_at: chrono::DateTime<chrono::Utc>,
@@ -191,6 +320,9 @@ pub struct OidcClaims {
     pub preferred_username: Option<String>,
     pub upn: Option<String>,  // User Principal Name
     pub tid: Option<String>,  // Tenant ID
+    /// Claims without a field of their own, for claim mappings
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 impl OidcManager {
@@ -248,6 +380,7 @@ impl OidcManager {
                 ],
                 default_role: UserRole::Denied,
                 group_prefix: Some("GhostLink-".to_string()),
+                mappings: Vec::new(),
             },
             session_config: SessionConfig {
                 session_timeout_minutes: 480,  // 8 hours
@@ -325,6 +458,10 @@ impl OidcManager {
         if !config.authority.starts_with("https://") {
             return Err("Authority URL must use HTTPS".to_string());
         }
+
+        if config.role_mapping.mappings.iter().any(|m| m.claim.is_empty() || m.value.is_empty()) {
+            return Err("Claim mappings need a claim and a value".to_string());
+        }
         
         Ok(())
     }
@@ -363,18 +500,26 @@ impl OidcManager {
         Ok(auth_url)
     }
     
-    /// Handle authorization code callback
-    pub async fn handle_callback(&self, code: AuthorizationCode) -> Result<UserSession, String> {
+    /// Handle authorization code callback, creating or updating the user
+    /// from the ID token's claims
+    pub async fn handle_callback(
+        &self,
+        code: AuthorizationCode,
+        db: Option<&DatabaseService>,
+    ) -> Result<(UserSession, User), String> {
         let config = self.config.read().await;
+        let db = db.ok_or("OIDC sign-in needs a database to keep users in")?;
         
         // Exchange code for tokens
         let token_response = self.exchange_code_for_tokens(&config, &code.code).await?;
         
         // Validate and decode ID token
-        let claims = self.validate_id_token(&token_response.id_token).await?;
+        let claims = self.validate_id_token(&config, &token_response.id_token).await?;
+        
+        let (user, grant) = provision_user(db, &config, &claims).await?;
         
         // Map claims to user session
-        let session = self.create_user_session(&config, claims, token_response).await?;
+        let session = self.create_user_session(&config, &user, grant, claims, token_response);
         
         // Store session
         {
@@ -382,8 +527,8 @@ impl OidcManager {
             sessions.insert(session.session_id.clone(), session.clone());
         }
         
-        info!("User {} authenticated successfully", session.email);
-        Ok(session)
+        info!("User {} authenticated successfully as {}", session.email, session.role);
+        Ok((session, user))
     }
     
     /// Exchange authorization code for tokens
@@ -420,7 +565,7 @@ impl OidcManager {
     }
     
     /// Validate and decode ID token
-    async fn validate_id_token(&self, id_token: &str) -> Result<OidcClaims, String> {
+    async fn validate_id_token(&self, config: &OidcConfig, id_token: &str) -> Result<OidcClaims, String> {
         // Refresh JWKS cache if needed
         self.refresh_jwks_cache_if_needed().await?;
         
@@ -436,48 +581,34 @@ impl OidcManager {
         
         // Validate token
         let decoding_key = self.jwk_to_decoding_key(key)?;
-        let validation = Validation::new(Algorithm::RS256);
-        
-        let token_data = decode::<OidcClaims>(id_token, &decoding_key, &validation)
-            .map_err(|e| format!("Token validation failed: {}", e))?;
-        
-        Ok(token_data.claims)
+        decode_id_token(id_token, &decoding_key, Algorithm::RS256, &config.client_id)
     }
     
     /// Create user session from claims
-    async fn create_user_session(
+    fn create_user_session(
         &self, 
         config: &OidcConfig, 
+        user: &User,
+        grant: Grant,
         claims: OidcClaims,
         token_response: TokenResponse
-    ) -> Result<UserSession, String> {
-        // Map claims using configuration
-        let user_id = claims.sub;
-        let email = claims.email.unwrap_or_default();
-        let name = claims.name.unwrap_or_default();
+    ) -> UserSession {
         let groups = claims.groups.unwrap_or_default();
         
-        // Determine user roles
-        let roles = self.map_user_roles(&config.role_mapping, &groups).await;
-        
-        // Check if user has access
-        if roles.contains(&UserRole::Denied) {
-            return Err("Access denied - user not in authorized groups".to_string());
-        }
-        
         let session_id = Uuid::new_v4().to_string();
         let now = chrono::Utc::now();
         let expires_at = now + chrono::Duration::minutes(config.session_config.session_timeout_minutes as i64);
         
-        Ok(UserSession {
+        UserSession {
             session_id,
-            user_id,
-            email,
-            name,
+            user_id: user.id.to_string(),
+            email: user.email.clone().unwrap_or_default(),
+            name: user.full_name.clone().unwrap_or_default(),
             given_name: claims.given_name,
             family_name: claims.family_name,
             groups,
-            roles,
+            role: grant.role,
+            organization_id: grant.organization_id,
             department: claims.department,
             company: claims.company,
             created_at: now,
@@ -488,39 +619,7 @@ impl OidcManager {
             id_token: token_response.id_token,
             source_ip: None,
             user_agent: None,
-        })
-    }
-    
-    /// Map user groups to roles
-    async fn map_user_roles(&self, role_mapping: &RoleMapping, groups: &[String]) -> Vec<UserRole> {
-        let mut roles = vec![role_mapping.default_role.clone()];
-        
-        // Check for admin groups
-        for group in groups {
-            if role_mapping.admin_groups.contains(group) {
-                roles.push(UserRole::Admin);
-                break;
-            }
         }
-        
-        // Check for user groups
-        for group in groups {
-            if role_mapping.user_groups.contains(group) {
-                roles.push(UserRole::User);
-                break;
-            }
-        }
-        
-        // Check for readonly groups
-        for group in groups {
-            if role_mapping.readonly_groups.contains(group) {
-                roles.push(UserRole::ReadOnly);
-                break;
-            }
-        }
-        
-        roles.dedup();
-        roles
     }
     
     /// Refresh JWKS cache
@@ -683,6 +782,89 @@ impl OidcManager {
     }
 }
 
+/// Check the signature, expiry and audience of an ID token and read its claims
+fn decode_id_token(id_token: &str, key: &DecodingKey, algorithm: Algorithm, client_id: &str) -> Result<OidcClaims, String> {
+    let mut validation = Validation::new(algorithm);
+    validation.set_audience(&[client_id]);
+    
+    let token_data = decode::<OidcClaims>(id_token, key, &validation)
+        .map_err(|e| format!("Token validation failed: {}", e))?;
+    
+    Ok(token_data.claims)
+}
+
+/// Find or create the user an ID token belongs to and bring their role,
+/// device groups and organization in line with the claim mappings
+pub async fn provision_user(db: &DatabaseService, config: &OidcConfig, claims: &OidcClaims) -> Result<(User, Grant), String> {
+    let claims_json = serde_json::to_value(claims).map_err(|e| e.to_string())?;
+    let grant = config.role_mapping
+        .resolve(&claims_json, &config.claims_mapping.groups)
+        .ok_or("Access denied - no role mapping matches the user's claims")?;
+
+    let first = |name: &str| claim_values(&claims_json, name).into_iter().find(|value| !value.is_empty());
+    let email = first(&config.claims_mapping.email);
+    let full_name = first(&config.claims_mapping.name);
+    let role = grant.role.as_str().to_string();
+
+    let existing = db.get_user_by_oidc_subject(&claims.sub)
+        .await
+        .map_err(|e| format!("Failed to look up user: {}", e))?;
+    let user = match existing {
+        Some(mut user) => {
+            if !user.is_active {
+                return Err("Access denied - account is disabled".to_string());
+            }
+            if user.role != role {
+                info!("Role of {} changed from {} to {}", user.username, user.role, role);
+            }
+            db.update_oidc_user(user.id, email.as_deref(), full_name.as_deref(), &role, grant.approver)
+                .await
+                .map_err(|e| format!("Failed to update user: {}", e))?;
+            user.email = email;
+            user.full_name = full_name;
+            user.role = role;
+            user.is_approver = grant.approver;
+            user
+        }
+        None => {
+            let now = chrono::Utc::now();
+            let mut user = User {
+                id: Uuid::nil(),
+                username: first("preferred_username").or_else(|| email.clone()).unwrap_or_else(|| claims.sub.clone()),
+                email,
+                password_hash: None,
+                full_name,
+                role,
+                is_active: true,
+                last_login: None,
+                failed_login_attempts: 0,
+                locked_until: None,
+                oidc_subject: Some(claims.sub.clone()),
+                is_approver: grant.approver,
+                created_at: now,
+                updated_at: now,
+            };
+            user.id = db.create_user(&user)
+                .await
+                .map_err(|e| format!("Failed to create user {}: {}", user.username, e))?;
+            info!("Created user {} as {} on first OIDC login", user.username, user.role);
+            user
+        }
+    };
+
+    db.set_user_device_groups(user.id, &grant.device_groups)
+        .await
+        .map_err(|e| format!("Failed to update device groups of {}: {}", user.username, e))?;
+    db.set_user_organization(user.id, grant.organization_id)
+        .await
+        .map_err(|e| format!("Failed to update organization of {}: {}", user.username, e))?;
+    if let Err(e) = db.update_user_last_login(user.id).await {
+        tracing::warn!("Failed to record login of {}: {}", user.username, e);
+    }
+
+    Ok((user, grant))
+}
+
 /// NGINX auth request middleware for use with nginx auth_request directive
 #[allow(dead_code)]
 pub async fn nginx_auth_middleware(
@@ -711,14 +893,9 @@ pub async fn nginx_auth_middleware(
                 HeaderValue::from_str(&session.groups.join(",")).unwrap(),
             );
             
-            let roles_str = session.roles.iter()
-                .map(|r| format!("{:?}", r))
-                .collect::<Vec<_>>()
-                .join(",");
-            
             response_headers.insert(
                 config.nginx_integration.roles_header.parse::<axum::http::header::HeaderName>().unwrap(),
-                HeaderValue::from_str(&roles_str).unwrap(),
+                HeaderValue::from_str(session.role.as_str()).unwrap(),
             );
             
             (StatusCode::OK, response_headers, "").into_response()
@@ -749,13 +926,34 @@ pub async fn api_get_auth_url(
     }
 }
 
-/// Handle OAuth callback
+/// Handle OAuth callback; every attempt lands in the audit trail
 pub async fn api_oauth_callback(
     State(app_state): State<AppState>,
+    context: RequestContext,
     Query(code): Query<AuthorizationCode>,
 ) -> Response {
-    match app_state.device_manager.oidc_manager.handle_callback(code).await {
-        Ok(session) => {
+    let result = app_state.device_manager.oidc_manager.handle_callback(code, app_state.db.as_deref()).await;
+    let entry = match &result {
+        Ok((session, user)) => AuditLog::new("auth", "oidc_login_succeeded")
+            .actor(user.id.to_string())
+            .details(serde_json::json!({
+                "email": session.email,
+                "role": session.role,
+                "organization_id": session.organization_id,
+            })),
+        Err(e) => AuditLog::new("auth", "oidc_login_failed").details(serde_json::json!({ "error": e })),
+    };
+    app_state.device_manager.record_audit(entry.request(&context)).await;
+
+    let tokens = result.and_then(|(session, user)| {
+        let organization_id = session.organization_id.map(|id| id.to_string());
+        JwtService::new(&app_state.config.jwt_secret)
+            .generate_token_pair(&user.id, &session.email, session.role.as_str(), organization_id.as_deref(), &[])
+            .map(|tokens| (session, tokens))
+            .map_err(|e| format!("Failed to issue tokens: {}", e))
+    });
+    match tokens {
+        Ok((session, tokens)) => {
             // Set session cookie
             let cookie = format!(
                 "ghostlink_session={}; HttpOnly; Secure; SameSite=Lax; Path=/; Max-Age={}",
@@ -771,8 +969,10 @@ pub async fn api_oauth_callback(
                     "user": {
                         "email": session.email,
                         "name": session.name,
-                        "roles": session.roles
-                    }
+                        "role": session.role,
+                        "organization_id": session.organization_id
+                    },
+                    "tokens": tokens
                 }))
             ).into_response()
         }
@@ -800,12 +1000,24 @@ pub async fn api_get_oidc_config(
 /// Update OIDC configuration  
 pub async fn api_update_oidc_config(
     State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
     Json(config): Json<OidcConfig>,
 ) -> Response {
+    // Secrets in the new config are redacted when the entry is recorded
+    let details = serde_json::to_value(&config).unwrap_or_default();
     match app_state.device_manager.oidc_manager.update_config(config).await {
-        Ok(_) => Json(serde_json::json!({
-            "status": "updated"
-        })).into_response(),
+        Ok(_) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("config", "oidc_config_updated")
+                    .actor(user.user_id.to_string())
+                    .request(&context)
+                    .details(details),
+            ).await;
+            Json(serde_json::json!({
+                "status": "updated"
+            })).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -868,4 +1080,189 @@ pub async fn api_nginx_auth(
         "status": "error",
         "message": "Nginx auth not yet implemented"
     }))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use models::UserRole as Role;
+
+    const IDP_SECRET: &str = "test-idp-secret";
+
+    fn config(support_group: Uuid, organization_id: Uuid) -> OidcConfig {
+        let mut config = OidcManager::default_config();
+        config.role_mapping.mappings = vec![
+            ClaimMapping {
+                claim: "groups".to_string(),
+                value: "Support-Techs".to_string(),
+                role: Some(Role::Technician),
+                device_groups: vec![support_group],
+                organization_id: None,
+                approver: false,
+            },
+            ClaimMapping {
+                claim: "roles".to_string(),
+                value: "GhostLink.Operator".to_string(),
+                role: Some(Role::Operator),
+                device_groups: Vec::new(),
+                organization_id: None,
+                approver: false,
+            },
+            // Grants no role, only the tenant's organization
+            ClaimMapping {
+                claim: "tid".to_string(),
+                value: "contoso-tenant".to_string(),
+                role: None,
+                device_groups: Vec::new(),
+                organization_id: Some(organization_id),
+                approver: false,
+            },
+            // Grants no role, only the right to approve backstage sessions
+            ClaimMapping {
+                claim: "groups".to_string(),
+                value: "Backstage-Approvers".to_string(),
+                role: None,
+                device_groups: Vec::new(),
+                organization_id: None,
+                approver: true,
+            },
+        ];
+        config
+    }
+
+    /// An ID token from the fake IdP, read back the way a real one is
+    fn id_token(config: &OidcConfig, extra: serde_json::Value) -> OidcClaims {
+        let now = chrono::Utc::now().timestamp();
+        let mut claims = serde_json::json!({
+            "iss": "https://idp.example.com",
+            "aud": config.client_id,
+            "exp": now + 300,
+            "iat": now,
+        });
+        claims.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(IDP_SECRET.as_bytes())).unwrap();
+        decode_id_token(&token, &DecodingKey::from_secret(IDP_SECRET.as_bytes()), Algorithm::HS256, &config.client_id).unwrap()
+    }
+
+    fn grant(config: &OidcConfig, claims: &OidcClaims) -> Option<Grant> {
+        config.role_mapping.resolve(&serde_json::to_value(claims).unwrap(), &config.claims_mapping.groups)
+    }
+
+    #[test]
+    fn test_claims_map_to_role_groups_and_organization() {
+        let (support_group, organization_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let config = config(support_group, organization_id);
+
+        let tech = id_token(&config, serde_json::json!({"sub": "u1", "groups": ["Support-Techs"], "tid": "contoso-tenant"}));
+        assert_eq!(grant(&config, &tech), Some(Grant {
+            role: Role::Technician,
+            device_groups: vec![support_group],
+            organization_id: Some(organization_id),
+            approver: false,
+        }));
+
+        // Claims other than groups, matched without regard to case
+        let operator = id_token(&config, serde_json::json!({"sub": "u2", "roles": ["ghostlink.operator"]}));
+        assert_eq!(grant(&config, &operator).unwrap().role, Role::Operator);
+
+        let approver = id_token(&config, serde_json::json!({"sub": "u6", "groups": ["Support-Techs", "Backstage-Approvers"]}));
+        let approver = grant(&config, &approver).unwrap();
+        assert_eq!(approver.role, Role::Technician);
+        assert!(approver.approver);
+
+        // The legacy admin list still applies, and the most privileged role wins
+        let admin = id_token(&config, serde_json::json!({"sub": "u3", "groups": ["Support-Techs", "GhostLink-Admins"]}));
+        let admin = grant(&config, &admin).unwrap();
+        assert_eq!(admin.role, Role::Admin);
+        assert_eq!(admin.device_groups, vec![support_group]);
+        assert_eq!(admin.organization_id, None);
+    }
+
+    #[test]
+    fn test_unmapped_users_get_the_default_role() {
+        let mut config = config(Uuid::new_v4(), Uuid::new_v4());
+        let outsider = id_token(&config, serde_json::json!({"sub": "u4", "groups": ["Marketing"], "tid": "contoso-tenant"}));
+        // A mapping without a role does not let anyone in
+        assert_eq!(grant(&config, &outsider), None);
+
+        config.role_mapping.default_role = UserRole::ReadOnly;
+        assert_eq!(grant(&config, &outsider).unwrap().role, Role::Viewer);
+    }
+
+    #[test]
+    fn test_id_token_for_another_client_is_rejected() {
+        let config = config(Uuid::new_v4(), Uuid::new_v4());
+        let now = chrono::Utc::now().timestamp();
+        let claims = serde_json::json!({"iss": "https://idp.example.com", "sub": "u5", "aud": "another-app", "exp": now + 300, "iat": now});
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(IDP_SECRET.as_bytes())).unwrap();
+        assert!(decode_id_token(&token, &DecodingKey::from_secret(IDP_SECRET.as_bytes()), Algorithm::HS256, &config.client_id).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_logins_create_and_update_the_user() {
+        let Some(db) = crate::database::test_database().await else { return };
+        let now = chrono::Utc::now();
+        let group = models::DeviceGroup {
+            id: Uuid::new_v4(),
+            organization_id: None,
+            name: format!("Support {}", Uuid::new_v4()),
+            description: None,
+            created_at: now,
+            updated_at: now,
+        };
+        db.insert_device_group(&group).await.unwrap();
+        let organization_id = db.create_organization(&models::Organization {
+            id: Uuid::new_v4(),
+            name: "Contoso".to_string(),
+            slug: format!("contoso-{}", Uuid::new_v4()),
+            settings: sqlx::types::Json(HashMap::new()),
+            created_at: now,
+            updated_at: now,
+        }).await.unwrap();
+        let config = config(group.id, organization_id);
+
+        let subject = Uuid::new_v4().to_string();
+        let email = format!("{}@contoso.com", subject);
+        let first = id_token(&config, serde_json::json!({
+            "sub": subject, "email": email, "name": "Ada Lovelace",
+            "groups": ["Support-Techs"], "tid": "contoso-tenant",
+        }));
+        let (user, _) = provision_user(&db, &config, &first).await.unwrap();
+        let stored = db.get_user_by_id(user.id).await.unwrap().unwrap();
+        assert_eq!(stored.username, email);
+        assert_eq!(stored.email.as_deref(), Some(email.as_str()));
+        assert_eq!(stored.full_name.as_deref(), Some("Ada Lovelace"));
+        assert_eq!(stored.role, "technician");
+        assert_eq!(stored.oidc_subject.as_deref(), Some(subject.as_str()));
+        assert!(!stored.is_approver);
+        assert!(stored.password_hash.is_none());
+        assert!(stored.last_login.is_some());
+        assert_eq!(db.get_user_device_groups(user.id).await.unwrap(), vec![group.id]);
+        assert_eq!(db.get_user_organization(user.id).await.unwrap(), Some(organization_id));
+        let agent = crate::database::fixtures::agent("support-desk");
+        db.upsert_agent(&agent).await.unwrap();
+        db.set_agent_group(agent.id, Some(group.id)).await.unwrap();
+        assert!(db.user_reaches_agent_through_group(user.id, agent.id).await.unwrap());
+
+        // Promoted in the IdP and moved out of the tenant's mapping
+        let promoted = id_token(&config, serde_json::json!({
+            "sub": subject, "email": email, "name": "Ada King",
+            "groups": ["GhostLink-Admins", "Backstage-Approvers"],
+        }));
+        let (again, grant) = provision_user(&db, &config, &promoted).await.unwrap();
+        assert_eq!(again.id, user.id);
+        assert_eq!(grant.role, Role::Admin);
+        let stored = db.get_user_by_id(user.id).await.unwrap().unwrap();
+        assert_eq!(stored.role, "admin");
+        assert_eq!(stored.full_name.as_deref(), Some("Ada King"));
+        assert!(stored.is_approver);
+        assert!(db.get_user_device_groups(user.id).await.unwrap().is_empty());
+        assert!(!db.user_reaches_agent_through_group(user.id, agent.id).await.unwrap());
+        assert_eq!(db.get_user_organization(user.id).await.unwrap(), None);
+
+        // Removed from every mapped group
+        let removed = id_token(&config, serde_json::json!({"sub": subject, "email": email, "groups": []}));
+        assert!(provision_user(&db, &config, &removed).await.is_err());
+        assert_eq!(db.get_user_by_id(user.id).await.unwrap().unwrap().role, "admin");
+    }
+}
@@ -0,0 +1,367 @@
+//! Scoped tokens for joining sessions
+//!
+//! Creating a session mints a token bound to the session, the technician
+//! and the scopes they were granted (view, control, file, terminal), and
+//! the launch URL carries it. The session WebSocket redeems it once to
+//! attach; the attached viewer then works under the token's scopes and
+//! asks for more time over the socket before it runs out. Ending a session
+//! revokes its unused tokens and any further refreshes.
+//!
+//! Tokens are signed with an Ed25519 key derived from the JWT secret, so
+//! clients can check them against the public key before they connect.
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::auth::jwt::error;
+use crate::file_browser;
+use crate::models::SessionType;
+use crate::AppState;
+
+/// `purpose` claim that distinguishes session tokens from other tokens
+const SESSION_TOKEN_PURPOSE: &str = "session-join";
+
+/// Lifetime of a token when the request names none
+pub const DEFAULT_TOKEN_TTL_MINUTES: u32 = 15;
+
+/// Longest lifetime a request may ask for
+pub const MAX_TOKEN_TTL_MINUTES: u32 = 24 * 60;
+
+/// PKCS#8 v1 header of an Ed25519 private key; the 32-byte seed follows
+const PKCS8_ED25519_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// What a session token lets the viewer do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionScope {
+    /// Watch the screen, chat and tune the stream
+    View,
+    /// Send input and clipboard contents, and take control
+    Control,
+    /// Transfer files
+    #[serde(alias = "files")]
+    File,
+    /// Open terminals
+    Terminal,
+}
+
+impl SessionScope {
+    /// Scopes a session of `session_type` grants unless the request narrows them
+    pub fn defaults_for(session_type: &SessionType) -> Vec<SessionScope> {
+        match session_type {
+            SessionType::View => vec![SessionScope::View],
+            SessionType::FileTransfer => vec![SessionScope::View, SessionScope::File],
+            _ => vec![SessionScope::View, SessionScope::Control, SessionScope::File, SessionScope::Terminal],
+        }
+    }
+
+    /// Scope a viewer needs to send a command of `cmd_type`; anything not
+    /// listed only needs to view
+    pub fn for_command(cmd_type: &str) -> SessionScope {
+        match cmd_type {
+            "ClipboardSync" | "MonitorControl" | "request_control" | "grant_control" | "deny_control"
+            | "release_control" | "set_input_mode" | "set_input_preview" => SessionScope::Control,
+            t if t.starts_with("FileTransfer") => SessionScope::File,
+            t if file_browser::is_fs_command(t) => SessionScope::File,
+            t if t.starts_with("Terminal") => SessionScope::Terminal,
+            _ => SessionScope::View,
+        }
+    }
+}
+
+/// Scopes to grant a session of `session_type`: those requested, which
+/// must be among the type's defaults, or else all of the defaults
+pub fn grant_scopes(session_type: &SessionType, requested: Option<&[SessionScope]>) -> Result<Vec<SessionScope>, String> {
+    let defaults = SessionScope::defaults_for(session_type);
+    let Some(requested) = requested else {
+        return Ok(defaults);
+    };
+    if requested.is_empty() {
+        return Err("At least one scope is required".to_string());
+    }
+    if let Some(scope) = requested.iter().find(|scope| !defaults.contains(scope)) {
+        return Err(format!("Scope {:?} is not available to {} sessions", scope, session_type));
+    }
+    Ok(defaults.into_iter().filter(|scope| requested.contains(scope)).collect())
+}
+
+/// Token lifetime for a request naming `minutes`, or the default
+pub fn token_ttl(minutes: Option<u32>) -> Result<Duration, String> {
+    match minutes.unwrap_or(DEFAULT_TOKEN_TTL_MINUTES) {
+        minutes @ 1..=MAX_TOKEN_TTL_MINUTES => Ok(Duration::minutes(minutes as i64)),
+        _ => Err(format!("Token lifetime must be between 1 and {} minutes", MAX_TOKEN_TTL_MINUTES)),
+    }
+}
+
+/// What a session token vouches for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionTokenClaims {
+    pub purpose: String,
+    pub session_id: Uuid,
+    /// The technician the token was issued to
+    pub user_id: Uuid,
+    pub scopes: Vec<SessionScope>,
+    pub jti: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+impl SessionTokenClaims {
+    pub fn allows(&self, scope: SessionScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.exp, 0).unwrap_or_default()
+    }
+
+    /// Time left at `now`, zero once expired
+    pub fn remaining(&self, now: DateTime<Utc>) -> std::time::Duration {
+        (self.expires_at() - now).to_std().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionTokenError {
+    InvalidToken,
+    TokenExpired,
+    /// The token was issued for another session
+    WrongSession,
+    /// The token already attached a viewer
+    AlreadyUsed,
+    /// The session ended, taking its tokens with it
+    Revoked,
+}
+
+impl std::fmt::Display for SessionTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionTokenError::InvalidToken => write!(f, "invalid session token"),
+            SessionTokenError::TokenExpired => write!(f, "session token expired"),
+            SessionTokenError::WrongSession => write!(f, "session token was issued for another session"),
+            SessionTokenError::AlreadyUsed => write!(f, "session token was already used"),
+            SessionTokenError::Revoked => write!(f, "session has ended"),
+        }
+    }
+}
+
+impl IntoResponse for SessionTokenError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            SessionTokenError::WrongSession => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        error(status, self.to_string())
+    }
+}
+
+/// Mints, redeems and revokes session tokens
+pub struct SessionTokens {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    public_key: Vec<u8>,
+    /// Unused tokens of every live session; a session missing here was revoked
+    sessions: RwLock<HashMap<Uuid, HashSet<Uuid>>>,
+}
+
+impl SessionTokens {
+    pub fn new(secret: &str) -> Self {
+        let seed = digest(&SHA256, format!("ghostlink-session-tokens:{}", secret).as_bytes());
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed.as_ref())
+            .expect("a SHA-256 digest is a valid Ed25519 seed");
+        let public_key = key_pair.public_key().as_ref().to_vec();
+        let pkcs8 = [PKCS8_ED25519_PREFIX.as_slice(), seed.as_ref()].concat();
+
+        Self {
+            encoding_key: EncodingKey::from_ed_der(&pkcs8),
+            decoding_key: DecodingKey::from_ed_der(&public_key),
+            public_key,
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Raw Ed25519 key clients check tokens against
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Sign a token for `session_id` that lives for `ttl` from `now`
+    pub async fn mint(
+        &self,
+        session_id: Uuid,
+        user_id: Uuid,
+        scopes: Vec<SessionScope>,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<(String, SessionTokenClaims), SessionTokenError> {
+        let claims = SessionTokenClaims {
+            purpose: SESSION_TOKEN_PURPOSE.to_string(),
+            session_id,
+            user_id,
+            scopes,
+            jti: Uuid::new_v4(),
+            iat: now.timestamp(),
+            exp: (now + ttl).timestamp(),
+        };
+        let token = encode(&Header::new(Algorithm::EdDSA), &claims, &self.encoding_key)
+            .map_err(|_| SessionTokenError::InvalidToken)?;
+        self.sessions.write().await.entry(session_id).or_default().insert(claims.jti);
+        Ok((token, claims))
+    }
+
+    /// Check a token presented to attach to `session_id` and use it up
+    pub async fn redeem(&self, token: &str, session_id: Uuid) -> Result<SessionTokenClaims, SessionTokenError> {
+        let claims = self.verify(token)?;
+        if claims.session_id != session_id {
+            return Err(SessionTokenError::WrongSession);
+        }
+
+        let mut sessions = self.sessions.write().await;
+        let unused = sessions.get_mut(&session_id).ok_or(SessionTokenError::Revoked)?;
+        if !unused.remove(&claims.jti) {
+            return Err(SessionTokenError::AlreadyUsed);
+        }
+        Ok(claims)
+    }
+
+    /// Extend an attached viewer's grant by its original lifetime from `now`
+    pub async fn refresh(&self, grant: &SessionTokenClaims, now: DateTime<Utc>) -> Result<SessionTokenClaims, SessionTokenError> {
+        if !self.sessions.read().await.contains_key(&grant.session_id) {
+            return Err(SessionTokenError::Revoked);
+        }
+        if grant.expires_at() <= now {
+            return Err(SessionTokenError::TokenExpired);
+        }
+        Ok(SessionTokenClaims {
+            jti: Uuid::new_v4(),
+            iat: now.timestamp(),
+            exp: now.timestamp() + (grant.exp - grant.iat),
+            ..grant.clone()
+        })
+    }
+
+    /// Invalidate every unused token and further refreshes of a session
+    pub async fn revoke(&self, session_id: Uuid) {
+        self.sessions.write().await.remove(&session_id);
+    }
+
+    fn verify(&self, token: &str) -> Result<SessionTokenClaims, SessionTokenError> {
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.leeway = 0;
+        let claims = decode::<SessionTokenClaims>(token, &self.decoding_key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => SessionTokenError::TokenExpired,
+                _ => SessionTokenError::InvalidToken,
+            })?
+            .claims;
+
+        if claims.purpose != SESSION_TOKEN_PURPOSE {
+            return Err(SessionTokenError::InvalidToken);
+        }
+        Ok(claims)
+    }
+}
+
+/// Where a viewer attaches with `token`
+pub fn launch_url(session_id: Uuid, token: &str) -> String {
+    format!("/api/ws?session_id={}&token={}", session_id, token)
+}
+
+/// Public key for checking session tokens
+pub async fn api_session_token_key(State(app_state): State<AppState>) -> Response {
+    let public_key = app_state.device_manager.session_tokens.public_key();
+    Json(serde_json::json!({
+        "algorithm": "EdDSA",
+        "public_key": URL_SAFE_NO_PAD.encode(public_key),
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn mint(tokens: &SessionTokens, session_id: Uuid, ttl: Duration, now: DateTime<Utc>) -> (String, SessionTokenClaims) {
+        tokens.mint(session_id, Uuid::new_v4(), vec![SessionScope::View], ttl, now).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_single_use_and_bound_to_their_session() {
+        let tokens = SessionTokens::new("test-secret");
+        let session_id = Uuid::new_v4();
+        let (token, claims) = mint(&tokens, session_id, Duration::minutes(15), Utc::now()).await;
+
+        assert_eq!(tokens.redeem(&token, Uuid::new_v4()).await, Err(SessionTokenError::WrongSession));
+        assert_eq!(tokens.redeem(&token, session_id).await, Ok(claims));
+        assert_eq!(tokens.redeem(&token, session_id).await, Err(SessionTokenError::AlreadyUsed));
+
+        // Signed with another server's key
+        let (foreign, _) = mint(&SessionTokens::new("other-secret"), session_id, Duration::minutes(15), Utc::now()).await;
+        assert_eq!(tokens.redeem(&foreign, session_id).await, Err(SessionTokenError::InvalidToken));
+    }
+
+    #[tokio::test]
+    async fn test_expired_tokens_are_rejected() {
+        let tokens = SessionTokens::new("test-secret");
+        let session_id = Uuid::new_v4();
+        let issued = Utc::now() - Duration::minutes(20);
+        let (token, grant) = mint(&tokens, session_id, Duration::minutes(15), issued).await;
+
+        assert_eq!(tokens.redeem(&token, session_id).await, Err(SessionTokenError::TokenExpired));
+        assert_eq!(tokens.refresh(&grant, Utc::now()).await, Err(SessionTokenError::TokenExpired));
+        assert_eq!(grant.remaining(Utc::now()), std::time::Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_extends_until_revoked() {
+        let tokens = SessionTokens::new("test-secret");
+        let session_id = Uuid::new_v4();
+        // Tokens carry whole seconds
+        let now = DateTime::from_timestamp(Utc::now().timestamp(), 0).unwrap();
+        let (token, grant) = mint(&tokens, session_id, Duration::minutes(15), now).await;
+        let (unused, _) = mint(&tokens, session_id, Duration::minutes(15), now).await;
+        tokens.redeem(&token, session_id).await.unwrap();
+
+        let later = now + Duration::minutes(10);
+        let refreshed = tokens.refresh(&grant, later).await.unwrap();
+        assert_eq!(refreshed.expires_at(), later + Duration::minutes(15));
+        assert_eq!((refreshed.session_id, refreshed.user_id, &refreshed.scopes), (grant.session_id, grant.user_id, &grant.scopes));
+
+        tokens.revoke(session_id).await;
+        assert_eq!(tokens.redeem(&unused, session_id).await, Err(SessionTokenError::Revoked));
+        assert_eq!(tokens.refresh(&refreshed, later).await, Err(SessionTokenError::Revoked));
+    }
+
+    #[test]
+    fn test_commands_need_their_scope() {
+        assert_eq!(SessionScope::for_command("ClipboardSync"), SessionScope::Control);
+        assert_eq!(SessionScope::for_command("set_input_mode"), SessionScope::Control);
+        assert_eq!(SessionScope::for_command("set_input_preview"), SessionScope::Control);
+        assert_eq!(SessionScope::for_command("FileTransferResume"), SessionScope::File);
+        assert_eq!(SessionScope::for_command("Delete"), SessionScope::File);
+        assert_eq!(SessionScope::for_command("TerminalInput"), SessionScope::Terminal);
+        assert_eq!(SessionScope::for_command("request_keyframe"), SessionScope::View);
+        assert_eq!(SessionScope::defaults_for(&SessionType::View), vec![SessionScope::View]);
+
+        assert_eq!(grant_scopes(&SessionType::Control, Some(&[SessionScope::View])), Ok(vec![SessionScope::View]));
+        assert!(grant_scopes(&SessionType::View, Some(&[SessionScope::Control])).is_err());
+        assert!(grant_scopes(&SessionType::Control, Some(&[])).is_err());
+        assert_eq!(token_ttl(None), Ok(Duration::minutes(DEFAULT_TOKEN_TTL_MINUTES as i64)));
+        assert!(token_ttl(Some(0)).is_err());
+    }
+}
@@ -11,7 +11,11 @@ use tokio::sync::RwLock;
 use uuid::Uuid;
 use tracing::{info, debug};
 
-use crate::AppState;
+use crate::audit::RequestContext;
+use crate::auth::jwt::AuthUser;
+use crate::api::session_in_tenant;
+use crate::organizations::{self, Tenant};
+use crate::{models::AuditLog, AppState};
 
 /// Connection banner and branding manager
 pub struct BrandingManager {
@@ -19,6 +23,9 @@ pub struct BrandingManager {
     banners: Arc<RwLock<HashMap<Uuid, ConnectionBanner>>>,
     /// Global branding configuration
     global_config: Arc<RwLock<BrandingConfig>>,
+    /// Branding of organizations that replaced the global one, indexed by
+    /// organization ID
+    organization_configs: Arc<RwLock<HashMap<Uuid, BrandingConfig>>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,6 +43,9 @@ pub struct ConnectionBanner {
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
     pub acknowledgment_required: bool,
+    /// How long the end user has to accept before the session is cancelled
+    #[serde(default)]
+    pub acknowledgment_timeout_secs: Option<u32>,
     pub acknowledged_by: Vec<String>,
 }
 
@@ -142,6 +152,58 @@ pub struct BrandingConfig {
     pub footer_text: Option<String>,
     pub terms_of_service_url: Option<String>,
     pub privacy_policy_url: Option<String>,
+    /// Banner shown on the endpoint when a session starts
+    #[serde(default)]
+    pub session_banner: SessionBannerPolicy,
+}
+
+/// Whether sessions start with a banner on the endpoint, and whether the
+/// end user must accept it before anything is captured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionBannerPolicy {
+    pub enabled: bool,
+    pub acknowledgment_required: bool,
+    pub acknowledgment_timeout_secs: u32,
+}
+
+impl Default for SessionBannerPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            acknowledgment_required: false,
+            acknowledgment_timeout_secs: 120,
+        }
+    }
+}
+
+/// Colors a banner is drawn in on the endpoint
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BannerColors {
+    pub background: String,
+    pub text: String,
+    pub accent: String,
+}
+
+impl BannerTheme {
+    /// Resolve the theme against the branding colors
+    pub fn colors(&self, config: &BrandingConfig) -> BannerColors {
+        let colors = |background: &str, text: &str, accent: &str| BannerColors {
+            background: background.to_string(),
+            text: text.to_string(),
+            accent: accent.to_string(),
+        };
+        match self {
+            BannerTheme::Professional => colors(&config.primary_color, "#ffffff", &config.accent_color),
+            BannerTheme::Security => colors("#dc3545", "#ffffff", "#ffc107"),
+            BannerTheme::Success => colors("#198754", "#ffffff", &config.accent_color),
+            BannerTheme::Warning => colors("#ffc107", "#212529", "#dc3545"),
+            BannerTheme::Dark => colors("#212529", "#f8f9fa", &config.accent_color),
+            BannerTheme::Light => colors("#f8f9fa", "#212529", &config.primary_color),
+            BannerTheme::Custom { background_color, text_color, accent_color, .. } => {
+                colors(background_color, text_color, accent_color)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -169,6 +231,7 @@ impl BrandingManager {
         Self {
             banners: Arc::new(RwLock::new(HashMap::new())),
             global_config: Arc::new(RwLock::new(Self::default_branding_config())),
+            organization_configs: Arc::new(RwLock::new(HashMap::new())),
         }
     }
     
@@ -205,6 +268,7 @@ impl BrandingManager {
             footer_text: Some("Powered by GhostLink".to_string()),
             terms_of_service_url: None,
             privacy_policy_url: None,
+            session_banner: SessionBannerPolicy::default(),
         }
     }
     
@@ -224,6 +288,7 @@ impl BrandingManager {
             created_at: chrono::Utc::now(),
             expires_at: banner_request.expires_at,
             acknowledgment_required: banner_request.acknowledgment_required,
+            acknowledgment_timeout_secs: banner_request.acknowledgment_timeout_secs,
             acknowledged_by: Vec::new(),
         };
         
@@ -260,25 +325,39 @@ impl BrandingManager {
         }
     }
     
-    /// Get global branding configuration
-    pub async fn get_branding_config(&self) -> BrandingConfig {
+    /// Branding of `organization_id`, or the global branding for no
+    /// organization and organizations without their own
+    pub async fn get_branding_config(&self, organization_id: Option<Uuid>) -> BrandingConfig {
+        if let Some(organization_id) = organization_id {
+            if let Some(config) = self.organization_configs.read().await.get(&organization_id) {
+                return config.clone();
+            }
+        }
         self.global_config.read().await.clone()
     }
     
-    /// Update global branding configuration
-    pub async fn update_branding_config(&self, config: BrandingConfig) -> Result<(), String> {
-        {
-            let mut global_config = self.global_config.write().await;
-            *global_config = config;
+    /// Update the branding of `organization_id`, or the global branding
+    pub async fn update_branding_config(&self, organization_id: Option<Uuid>, config: BrandingConfig) -> Result<(), String> {
+        match organization_id {
+            Some(organization_id) => {
+                self.organization_configs.write().await.insert(organization_id, config);
+                info!("Updated branding configuration of organization {}", organization_id);
+            }
+            None => {
+                *self.global_config.write().await = config;
+                info!("Updated global branding configuration");
+            }
         }
-        
-        info!("Updated global branding configuration");
         Ok(())
     }
     
-    /// Create default connection banner
-    pub async fn create_default_connection_banner(&self, session_id: Uuid) -> Result<ConnectionBanner, String> {
-        let config = self.get_branding_config().await;
+    /// Create default connection banner with the branding of `organization_id`
+    pub async fn create_default_connection_banner(
+        &self,
+        session_id: Uuid,
+        organization_id: Option<Uuid>,
+    ) -> Result<ConnectionBanner, String> {
+        let config = self.get_branding_config(organization_id).await;
         
         let request = CreateBannerRequest {
             banner_type: BannerType::Connection,
@@ -310,15 +389,43 @@ impl BrandingManager {
                 audit_required: false,
             }),
             expires_at: Some(chrono::Utc::now() + chrono::Duration::hours(8)),
-            acknowledgment_required: false,
+            acknowledgment_required: config.session_banner.acknowledgment_required,
+            acknowledgment_timeout_secs: Some(config.session_banner.acknowledgment_timeout_secs),
         };
         
         self.create_connection_banner(session_id, request).await
     }
     
-    /// Generate CSS for custom theming
+    /// Banner a new session on a device of `organization_id` starts with, if
+    /// its branding config asks for one
+    pub async fn banner_for_new_session(&self, session_id: Uuid, organization_id: Option<Uuid>) -> Option<ConnectionBanner> {
+        if !self.get_branding_config(organization_id).await.session_banner.enabled {
+            return None;
+        }
+        self.create_default_connection_banner(session_id, organization_id).await.ok()
+    }
+    
+    /// The banner as the agent draws it, with the theme of `organization_id`
+    /// resolved to colors
+    pub async fn agent_banner(&self, banner: &ConnectionBanner, organization_id: Option<Uuid>) -> serde_json::Value {
+        let config = self.get_branding_config(organization_id).await;
+        serde_json::json!({
+            "id": banner.id,
+            "session_id": banner.session_id,
+            "title": banner.title,
+            "message": banner.message,
+            "company_name": banner.company_name,
+            "colors": banner.display_settings.theme.colors(&config),
+            "security_notice": banner.security_notice.as_ref().map(|notice| &notice.warning_text),
+            "acknowledgment_required": banner.acknowledgment_required,
+            "acknowledgment_timeout_secs": banner.acknowledgment_timeout_secs,
+            "auto_hide_seconds": banner.display_settings.auto_hide_seconds,
+        })
+    }
+    
+    /// Generate CSS for custom theming from the global branding
     pub async fn generate_theme_css(&self) -> String {
-        let config = self.get_branding_config().await;
+        let config = self.get_branding_config(None).await;
         
         format!(
             r#"
@@ -437,6 +544,8 @@ pub struct CreateBannerRequest {
     pub security_notice: Option<SecurityNotice>,
     pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
     pub acknowledgment_required: bool,
+    #[serde(default)]
+    pub acknowledgment_timeout_secs: Option<u32>,
 }
 
 /// API Handlers
@@ -444,9 +553,13 @@ pub struct CreateBannerRequest {
 /// Create connection banner
 pub async fn api_create_banner(
     State(app_state): State<AppState>,
+    tenant: Tenant,
     Path(session_id): Path<Uuid>,
     Json(request): Json<CreateBannerRequest>,
 ) -> Response {
+    if !session_in_tenant(&app_state, tenant, session_id).await {
+        return organizations::session_not_found(session_id).into_response();
+    }
     match app_state.device_manager.branding_manager.create_connection_banner(session_id, request).await {
         Ok(banner) => Json(banner).into_response(),
         Err(e) => (
@@ -461,8 +574,12 @@ pub async fn api_create_banner(
 /// Get session banner
 pub async fn api_get_session_banner(
     State(app_state): State<AppState>,
+    tenant: Tenant,
     Path(session_id): Path<Uuid>,
 ) -> Response {
+    if !session_in_tenant(&app_state, tenant, session_id).await {
+        return organizations::session_not_found(session_id).into_response();
+    }
     match app_state.device_manager.branding_manager.get_session_banner(session_id).await {
         Some(banner) => Json(banner).into_response(),
         None => (
@@ -503,23 +620,39 @@ pub async fn api_acknowledge_banner(
     }
 }
 
-/// Get branding configuration
+/// Get the branding of the caller's organization
 pub async fn api_get_branding_config(
     State(app_state): State<AppState>,
+    tenant: Tenant,
 ) -> impl IntoResponse {
-    let config = app_state.device_manager.branding_manager.get_branding_config().await;
+    let config = app_state.device_manager.branding_manager.get_branding_config(tenant.organization_id()).await;
     Json(config)
 }
 
-/// Update branding configuration
+/// Update the branding of the caller's organization; super-admins update
+/// the global branding
 pub async fn api_update_branding_config(
     State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
     Json(config): Json<BrandingConfig>,
 ) -> Response {
-    match app_state.device_manager.branding_manager.update_branding_config(config).await {
-        Ok(_) => Json(serde_json::json!({
-            "status": "updated"
-        })).into_response(),
+    // Secrets in the new config are redacted when the entry is recorded
+    let details = serde_json::to_value(&config).unwrap_or_default();
+    let organization_id = tenant.organization_id();
+    match app_state.device_manager.branding_manager.update_branding_config(organization_id, config).await {
+        Ok(_) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("config", "branding_config_updated")
+                    .actor(user.user_id.to_string())
+                    .request(&context)
+                    .details(details),
+            ).await;
+            Json(serde_json::json!({
+                "status": "updated"
+            })).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
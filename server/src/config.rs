@@ -1,27 +1,110 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::net::IpAddr;
+
+use crate::audit::AuditFileConfig;
+use crate::listen;
+use crate::rate_limit::RateLimitPolicy;
+use crate::recordings::{RecordingConfig, StorageBackend};
+use crate::recordings::storage::S3Config;
+use crate::relay::connection_broker::BrokerPolicy;
+use crate::relay::compression::CompressionPolicy;
+use crate::relay::control::SessionPolicy;
+use crate::relay::idle::{self, IdlePolicy};
+use crate::relay::input_coalescer;
+use crate::relay::liveness::LivenessPolicy;
+use crate::webhooks::WebhookPolicy;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
+    /// Address the relay's UDP lane binds, `::` (all of both families) by default
     pub host: String,
     pub port: u16,
-    pub database_url: String,
+    /// Whether listeners on `::` accept IPv4 clients as well
+    #[serde(default = "default_dual_stack")]
+    pub dual_stack: bool,
+    /// PostgreSQL connection string; state is kept in memory only when unset
+    pub database_url: Option<String>,
     pub jwt_secret: String,
     pub session_timeout: u64,
     pub max_concurrent_sessions: u32,
+    /// Accept agents that register without an enrollment signature, for
+    /// deployments still migrating older clients
+    #[serde(default)]
+    pub allow_unauthenticated_agents: bool,
+    /// Per-device session limits and control takeover behavior
+    #[serde(default)]
+    pub session_policy: SessionPolicy,
+    /// How long sessions may stay idle before the relay ends them
+    #[serde(default)]
+    pub idle: IdlePolicy,
+    /// When silent devices count as degraded or offline
+    #[serde(default)]
+    pub liveness: LivenessPolicy,
+    /// Audit trail storage used while no database is configured
+    #[serde(default)]
+    pub audit_file: AuditFileConfig,
+    /// Relay nodes sessions are placed on and how they are chosen
+    #[serde(default)]
+    pub relay_broker: BrokerPolicy,
+    /// UDP port hybrid sessions stream frames to; frames stay on the
+    /// WebSocket when unset
+    #[serde(default)]
+    pub relay_udp_port: Option<u16>,
+    /// Throttling of logins and WebSocket connections
+    #[serde(default)]
+    pub rate_limits: RateLimitPolicy,
+    /// Bearer token Prometheus must present to scrape `/metrics`; the
+    /// endpoint is open when unset
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+    /// Relay WebSocket URL device invitations provision agents with; taken
+    /// from the host the admin reached the server on when unset
+    #[serde(default)]
+    pub relay_url: Option<String>,
+    /// Milliseconds viewer pointer motion is held to coalesce before it is
+    /// forwarded to the agent; 0 forwards every batch at once
+    #[serde(default = "default_input_coalesce_ms")]
+    pub input_coalesce_ms: u64,
+    /// Retries of outbound webhooks and the internal networks they may reach
+    #[serde(default)]
+    pub webhooks: WebhookPolicy,
+    /// Where agents' session recordings are stored and how long they are kept
+    #[serde(default)]
+    pub recordings: RecordingConfig,
+    /// When JSON sent to agents that agreed to it is compressed
+    #[serde(default)]
+    pub compression: CompressionPolicy,
+}
+
+fn default_dual_stack() -> bool {
+    true
+}
+
+fn default_input_coalesce_ms() -> u64 {
+    input_coalescer::DEFAULT_WINDOW.as_millis() as u64
 }
 
 impl AppConfig {
+    /// Read the environment, refusing malformed listen addresses.
+    ///
+    /// `LEPTOS_SITE_ADDR` is the HTTP listener's `host:port` and is read by
+    /// Leptos; it is checked here so a bad value gets a readable error.
     pub fn load() -> Result<Self> {
-        Ok(AppConfig {
-            host: env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
+        if let Ok(site_addr) = env::var("LEPTOS_SITE_ADDR") {
+            listen::parse_socket_addr("LEPTOS_SITE_ADDR", &site_addr)?;
+        }
+        let config = AppConfig {
+            host: env::var("HOST").unwrap_or_else(|_| "::".to_string()),
             port: env::var("PORT")
                 .unwrap_or_else(|_| "8443".to_string())
                 .parse()
                 .unwrap_or(8443),
-            database_url: env::var("DATABASE_URL")
-                .unwrap_or_else(|_| "sqlite:./data/atlasconnect.db".to_string()),
+            dual_stack: env::var("DUAL_STACK")
+                .map(|value| !matches!(value.to_lowercase().as_str(), "0" | "false" | "no"))
+                .unwrap_or_else(|_| default_dual_stack()),
+            database_url: env::var("DATABASE_URL").ok().filter(|url| !url.is_empty()),
             jwt_secret: env::var("JWT_SECRET")
                 .unwrap_or_else(|_| "your-secret-key-here".to_string()),
             session_timeout: env::var("SESSION_TIMEOUT")
@@ -32,6 +115,265 @@ impl AppConfig {
                 .unwrap_or_else(|_| "100".to_string())
                 .parse()
                 .unwrap_or(100),
+            allow_unauthenticated_agents: env::var("ALLOW_UNAUTHENTICATED_AGENTS")
+                .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false),
+            session_policy: session_policy_from_env(),
+            idle: idle_policy_from_env(),
+            liveness: liveness_from_env(),
+            audit_file: audit_file_from_env(),
+            relay_broker: relay_broker_from_env(),
+            relay_udp_port: env::var("RELAY_UDP_PORT").ok().and_then(|port| port.parse().ok()),
+            rate_limits: rate_limits_from_env(),
+            metrics_token: env::var("METRICS_TOKEN").ok().filter(|token| !token.is_empty()),
+            relay_url: env::var("RELAY_URL").ok().filter(|url| !url.is_empty()),
+            input_coalesce_ms: env::var("INPUT_COALESCE_MS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_else(default_input_coalesce_ms),
+            webhooks: webhooks_from_env(),
+            recordings: recordings_from_env(),
+            compression: compression_from_env(),
+        };
+        config.host_ip()?;
+        Ok(config)
+    }
+
+    /// `host` as an address; IPv6 literals may be given in brackets
+    pub fn host_ip(&self) -> Result<IpAddr> {
+        listen::parse_host("HOST", &self.host)
+    }
+}
+
+/// A count from `name`, with 0 or `unlimited` lifting the limit
+fn limit(name: &str, default: Option<u32>) -> Option<u32> {
+    match env::var(name) {
+        Ok(value) if value.eq_ignore_ascii_case("unlimited") => None,
+        Ok(value) => match value.parse::<u32>() {
+            Ok(0) => None,
+            Ok(limit) => Some(limit),
+            Err(_) => default,
+        },
+        Err(_) => default,
+    }
+}
+
+/// `MAX_CONTROL_SESSIONS_PER_DEVICE` and `MAX_VIEW_SESSIONS_PER_DEVICE` take a
+/// count, with 0 or `unlimited` lifting the limit; `CONTROL_TAKEOVER_TIMEOUT`
+/// is in seconds and `CONTROL_TAKEOVER_POLICY` is `deny` or `grant`.
+fn session_policy_from_env() -> SessionPolicy {
+    let defaults = SessionPolicy::default();
+    SessionPolicy {
+        max_control_sessions: limit("MAX_CONTROL_SESSIONS_PER_DEVICE", defaults.max_control_sessions),
+        max_view_sessions: limit("MAX_VIEW_SESSIONS_PER_DEVICE", defaults.max_view_sessions),
+        takeover_timeout_secs: env::var("CONTROL_TAKEOVER_TIMEOUT")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.takeover_timeout_secs),
+        takeover_timeout: env::var("CONTROL_TAKEOVER_POLICY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.takeover_timeout),
+    }
+}
+
+/// `SESSION_IDLE_TIMEOUT` is in seconds, 0 never ending idle sessions;
+/// `SESSION_IDLE_TIMEOUT_<TYPE>` (e.g. `SESSION_IDLE_TIMEOUT_BACKSTAGE`)
+/// overrides it for one session type.
+fn idle_policy_from_env() -> IdlePolicy {
+    let mut policy = IdlePolicy::default();
+    if let Some(secs) = env::var("SESSION_IDLE_TIMEOUT").ok().and_then(|value| value.parse().ok()) {
+        policy.timeout_secs = secs;
+    }
+    for session_type in idle::SESSION_TYPES {
+        let name = format!("SESSION_IDLE_TIMEOUT_{}", session_type.to_uppercase());
+        if let Some(secs) = env::var(name).ok().and_then(|value| value.parse().ok()) {
+            policy.by_session_type.insert(session_type.to_string(), secs);
+        }
+    }
+    policy
+}
+
+/// `AUDIT_LOG_DIR` holds `audit.jsonl`, rotated at `AUDIT_LOG_MAX_BYTES` with
+/// `AUDIT_LOG_KEEP` older files kept.
+fn audit_file_from_env() -> AuditFileConfig {
+    let defaults = AuditFileConfig::default();
+    AuditFileConfig {
+        dir: env::var("AUDIT_LOG_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(Into::into)
+            .unwrap_or(defaults.dir),
+        max_bytes: env::var("AUDIT_LOG_MAX_BYTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.max_bytes),
+        keep: env::var("AUDIT_LOG_KEEP")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.keep),
+    }
+}
+
+/// `RELAY_NODE_SECRET` turns on brokering sessions onto relay nodes;
+/// `RELAY_BALANCING_STRATEGY` is `least_load`, `region_affinity` or
+/// `round_robin`. `RELAY_NODE_DRAIN_AFTER`, `RELAY_NODE_EVICT_AFTER` and
+/// `RELAY_ROUTE_TOKEN_TTL` are in seconds.
+fn relay_broker_from_env() -> BrokerPolicy {
+    let defaults = BrokerPolicy::default();
+    let secs = |name: &str, default: u64| env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+    BrokerPolicy {
+        node_secret: env::var("RELAY_NODE_SECRET").ok().filter(|secret| !secret.is_empty()),
+        strategy: env::var("RELAY_BALANCING_STRATEGY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.strategy),
+        drain_after_secs: secs("RELAY_NODE_DRAIN_AFTER", defaults.drain_after_secs),
+        evict_after_secs: secs("RELAY_NODE_EVICT_AFTER", defaults.evict_after_secs),
+        token_ttl_secs: secs("RELAY_ROUTE_TOKEN_TTL", defaults.token_ttl_secs),
+    }
+}
+
+/// `HEARTBEAT_INTERVAL_SECS` is the heartbeat interval agents use unless a
+/// policy sets one; `OFFLINE_AFTER_MISSED_HEARTBEATS` missed heartbeats take
+/// a device offline, and `DEVICE_STATUS_DEBOUNCE_SECS` is how long a new
+/// device status must hold before it is announced.
+fn liveness_from_env() -> LivenessPolicy {
+    let defaults = LivenessPolicy::default();
+    let number = |name: &str| env::var(name).ok().and_then(|value| value.parse::<u64>().ok());
+    LivenessPolicy {
+        heartbeat_interval_secs: number("HEARTBEAT_INTERVAL_SECS")
+            .filter(|secs| *secs > 0)
+            .unwrap_or(defaults.heartbeat_interval_secs),
+        offline_after_missed: number("OFFLINE_AFTER_MISSED_HEARTBEATS")
+            .and_then(|missed| u32::try_from(missed).ok())
+            .filter(|missed| *missed > 0)
+            .unwrap_or(defaults.offline_after_missed),
+        debounce_secs: number("DEVICE_STATUS_DEBOUNCE_SECS").unwrap_or(defaults.debounce_secs),
+    }
+}
+
+/// `AUTH_RATE_BURST`/`AUTH_RATE_PER_MINUTE` size the bucket of each client
+/// address and `LOGIN_RATE_BURST`/`LOGIN_RATE_PER_MINUTE` that of each
+/// account. `LOGIN_LOCKOUT_THRESHOLD` failed logins lock an account for
+/// `LOGIN_LOCKOUT_SECS`, doubling up to `LOGIN_LOCKOUT_MAX_SECS`.
+/// `MAX_CONNECTIONS_PER_IP` and `MAX_CONNECTIONS` cap WebSockets like the
+/// session limits above. `TRUSTED_PROXIES` lists the reverse proxies whose
+/// `X-Real-IP`/`X-Forwarded-For` is believed, comma-separated in CIDR
+/// notation; without it every request is keyed on its peer address.
+fn rate_limits_from_env() -> RateLimitPolicy {
+    let defaults = RateLimitPolicy::default();
+    let number = |name: &str, default: u32| env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+    let secs = |name: &str, default: u64| env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+    RateLimitPolicy {
+        ip_burst: number("AUTH_RATE_BURST", defaults.ip_burst),
+        ip_per_minute: number("AUTH_RATE_PER_MINUTE", defaults.ip_per_minute),
+        account_burst: number("LOGIN_RATE_BURST", defaults.account_burst),
+        account_per_minute: number("LOGIN_RATE_PER_MINUTE", defaults.account_per_minute),
+        lockout_threshold: number("LOGIN_LOCKOUT_THRESHOLD", defaults.lockout_threshold),
+        lockout_base_secs: secs("LOGIN_LOCKOUT_SECS", defaults.lockout_base_secs),
+        lockout_max_secs: secs("LOGIN_LOCKOUT_MAX_SECS", defaults.lockout_max_secs),
+        max_connections_per_ip: limit("MAX_CONNECTIONS_PER_IP", defaults.max_connections_per_ip),
+        max_connections: limit("MAX_CONNECTIONS", defaults.max_connections),
+        trusted_proxies: env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .filter_map(|network| match network.parse() {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    tracing::warn!("Ignoring TRUSTED_PROXIES entry {}: {}", network, e);
+                    None
+                }
+            })
+            .collect(),
+    }
+}
+
+/// `WEBHOOK_ALLOWED_NETWORKS` lists the internal networks webhooks may be
+/// delivered to anyway, comma-separated in CIDR notation, e.g.
+/// `10.20.0.0/16,fd12::/16`. `WEBHOOK_MAX_ATTEMPTS` deliveries are tried
+/// before they are given up on, each with `WEBHOOK_TIMEOUT_SECS` to answer.
+fn webhooks_from_env() -> WebhookPolicy {
+    let defaults = WebhookPolicy::default();
+    let allowed_networks = env::var("WEBHOOK_ALLOWED_NETWORKS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|network| !network.is_empty())
+        .filter_map(|network| match network.parse() {
+            Ok(network) => Some(network),
+            Err(e) => {
+                tracing::warn!("Ignoring WEBHOOK_ALLOWED_NETWORKS entry {}: {}", network, e);
+                None
+            }
         })
+        .collect();
+    WebhookPolicy {
+        allowed_networks,
+        max_attempts: env::var("WEBHOOK_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|attempts| *attempts > 0)
+            .unwrap_or(defaults.max_attempts),
+        timeout_secs: env::var("WEBHOOK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.timeout_secs),
+    }
+}
+
+/// `RECORDING_STORAGE` picks where recordings go: `local` (the default)
+/// keeps them under `RECORDING_DIR`, `s3` in `RECORDING_S3_BUCKET` at
+/// `RECORDING_S3_ENDPOINT`, signed with `RECORDING_S3_ACCESS_KEY` and
+/// `RECORDING_S3_SECRET_KEY` for `RECORDING_S3_REGION`, keys starting with
+/// `RECORDING_S3_PREFIX`. `RECORDING_RETENTION_DAYS` deletes them after that
+/// many days unless their organization says otherwise.
+fn recordings_from_env() -> RecordingConfig {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+    let storage = match var("RECORDING_STORAGE").map(|value| value.to_lowercase()).as_deref() {
+        Some("s3") => StorageBackend::S3(S3Config {
+            endpoint: var("RECORDING_S3_ENDPOINT").unwrap_or_else(|| "https://s3.amazonaws.com".to_string()),
+            bucket: var("RECORDING_S3_BUCKET").unwrap_or_default(),
+            region: var("RECORDING_S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+            access_key: var("RECORDING_S3_ACCESS_KEY").unwrap_or_default(),
+            secret_key: var("RECORDING_S3_SECRET_KEY").unwrap_or_default(),
+            prefix: var("RECORDING_S3_PREFIX").unwrap_or_default(),
+        }),
+        other => {
+            if let Some(other) = other.filter(|backend| *backend != "local") {
+                tracing::warn!("Unknown RECORDING_STORAGE {}; keeping recordings on local disk", other);
+            }
+            match var("RECORDING_DIR") {
+                Some(dir) => StorageBackend::Local { dir: dir.into() },
+                None => StorageBackend::default(),
+            }
+        }
+    };
+    RecordingConfig {
+        storage,
+        retention_days: var("RECORDING_RETENTION_DAYS").and_then(|days| days.parse().ok()).filter(|days| *days > 0),
+    }
+}
+
+/// JSON to agents of at least `COMPRESSION_THRESHOLD_BYTES` is compressed at
+/// zstd level `COMPRESSION_LEVEL`, unless that leaves more than
+/// `COMPRESSION_MAX_RATIO` of it.
+fn compression_from_env() -> CompressionPolicy {
+    let defaults = CompressionPolicy::default();
+    let var = |name: &str| env::var(name).ok();
+    CompressionPolicy {
+        threshold_bytes: var("COMPRESSION_THRESHOLD_BYTES")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.threshold_bytes),
+        level: var("COMPRESSION_LEVEL")
+            .and_then(|value| value.parse().ok())
+            .filter(|level| (1..=19).contains(level))
+            .unwrap_or(defaults.level),
+        max_ratio: var("COMPRESSION_MAX_RATIO")
+            .and_then(|value| value.parse().ok())
+            .filter(|ratio: &f64| *ratio > 0.0 && *ratio <= 1.0)
+            .unwrap_or(defaults.max_ratio),
     }
 }
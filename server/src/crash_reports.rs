@@ -0,0 +1,508 @@
+//! Agent crash reports
+//!
+//! When the agent panics, or dies of a fatal signal or exception, it leaves
+//! a crash report next to its logs: version, OS, backtrace, the last log
+//! lines and the sessions it was running, with credentials redacted. On its
+//! next start, and periodically after, it uploads the reports it has not
+//! sent to `POST /api/crash-reports`, if the device's policy (or, without
+//! one, the end user's setting) allows it. Uploads are signed with the
+//! device key from enrollment, like diagnostics bundles, and a retried
+//! upload is stored once.
+//!
+//! Reports are grouped by the topmost frame of GhostLink's own code, so the
+//! same bug on many devices shows up as one group. Heartbeats flag a crash
+//! too, which is how devices that crash without uploading still show up as
+//! unstable.
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as DbJson;
+use sqlx::FromRow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::enrollment::UploadKind;
+use crate::auth::jwt::error;
+use crate::database::DatabaseService;
+use crate::models::AuditLog;
+use crate::organizations::Tenant;
+use crate::AppState;
+
+/// Largest report accepted for upload, and the body limit of its route
+pub const MAX_REPORT_SIZE: usize = 1024 * 1024;
+
+/// Reports kept for each device
+const KEEP_PER_DEVICE: usize = 50;
+
+/// Longest group key; frames of generic code can run long
+const MAX_GROUP_KEY_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "VARCHAR", rename_all = "snake_case")]
+pub enum CrashKind {
+    /// A Rust panic, with a backtrace
+    Panic,
+    /// A fatal signal or unhandled exception, e.g. in encoder FFI code
+    Signal,
+}
+
+/// A crash report as the agent uploads it; the rest of the report is kept
+/// as sent
+#[derive(Debug, Clone, Deserialize)]
+pub struct CrashUpload {
+    pub agent_id: Uuid,
+    /// The agent's ID for the report
+    pub id: Uuid,
+    pub version: String,
+    pub os: String,
+    pub kind: CrashKind,
+    #[serde(default)]
+    pub message: String,
+    /// Topmost frame of GhostLink's own code, for panics with a backtrace
+    #[serde(default)]
+    pub top_frame: Option<String>,
+    /// Signal or exception code of a [`CrashKind::Signal`] crash
+    #[serde(default)]
+    pub signal: Option<i64>,
+    pub crashed_at: DateTime<Utc>,
+}
+
+impl CrashUpload {
+    /// What reports of the same crash have in common: the top frame, else
+    /// the signal, else the first line of the message
+    pub fn group_key(&self) -> String {
+        let key = match (&self.top_frame, self.kind, self.signal) {
+            (Some(frame), _, _) if !frame.trim().is_empty() => frame.trim().to_string(),
+            (_, CrashKind::Signal, Some(signal)) => format!("signal {}", signal),
+            _ => self.message.lines().next().unwrap_or("").trim().to_string(),
+        };
+        match key.char_indices().nth(MAX_GROUP_KEY_LENGTH) {
+            Some((end, _)) => key[..end].to_string(),
+            None if key.is_empty() => "unknown".to_string(),
+            None => key,
+        }
+    }
+}
+
+/// A stored crash report
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct CrashReport {
+    pub id: Uuid,
+    pub agent_id: Uuid,
+    pub organization_id: Option<Uuid>,
+    /// The agent's ID for the report
+    pub report_id: Uuid,
+    pub version: String,
+    pub os: String,
+    pub kind: CrashKind,
+    pub message: String,
+    pub group_key: String,
+    /// The report as uploaded; only its own endpoint returns it
+    #[serde(skip_serializing)]
+    pub report: DbJson<serde_json::Value>,
+    /// Bytes uploaded
+    pub size: i64,
+    pub crashed_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Reports sharing a group key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrashGroup {
+    pub group_key: String,
+    pub count: usize,
+    /// Devices that crashed this way
+    pub devices: usize,
+    /// Agent versions that crashed this way, newest report's first
+    pub versions: Vec<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Newest report of the group
+    pub latest_id: Uuid,
+}
+
+/// How often a device crashed, for highlighting unstable devices
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrashSummary {
+    /// Reports stored for the device
+    pub reports: usize,
+    /// Last crash a report or heartbeat told of
+    pub last_crash_at: Option<DateTime<Utc>>,
+}
+
+/// Criteria for listing reports
+#[derive(Debug, Default, Clone)]
+pub struct CrashFilter {
+    pub tenant: Tenant,
+    pub agent_id: Option<Uuid>,
+    pub group_key: Option<String>,
+    /// Only crashes at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only crashes before this time
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl CrashFilter {
+    fn matches(&self, report: &CrashReport) -> bool {
+        self.tenant.includes(report.organization_id)
+            && self.agent_id.is_none_or(|agent_id| report.agent_id == agent_id)
+            && self.group_key.as_ref().is_none_or(|key| &report.group_key == key)
+            && self.from.is_none_or(|from| report.crashed_at >= from)
+            && self.to.is_none_or(|to| report.crashed_at < to)
+    }
+}
+
+/// Crash reports of every device, and when each last crashed
+pub struct CrashReportStore {
+    db: Option<Arc<DatabaseService>>,
+    reports: RwLock<HashMap<Uuid, CrashReport>>,
+    /// Latest crash per device, from reports and heartbeats
+    last_crash: RwLock<HashMap<Uuid, DateTime<Utc>>>,
+    keep: usize,
+}
+
+impl Default for CrashReportStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CrashReportStore {
+    pub fn new() -> Self {
+        Self {
+            db: None,
+            reports: RwLock::new(HashMap::new()),
+            last_crash: RwLock::new(HashMap::new()),
+            keep: KEEP_PER_DEVICE,
+        }
+    }
+
+    /// Keep reports in `db`
+    pub fn with_database(mut self, db: Arc<DatabaseService>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Load the reports a previous run stored
+    pub async fn initialize(&self) -> Result<(), String> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let reports = db.list_crash_reports().await.map_err(|e| format!("Failed to load crash reports: {}", e))?;
+        info!("Loaded {} crash reports", reports.len());
+        let mut last_crash = self.last_crash.write().await;
+        for report in &reports {
+            let latest = last_crash.entry(report.agent_id).or_insert(report.crashed_at);
+            *latest = (*latest).max(report.crashed_at);
+        }
+        self.reports.write().await.extend(reports.into_iter().map(|report| (report.id, report)));
+        Ok(())
+    }
+
+    /// Store an uploaded report, `raw` being the body it came in. Returns
+    /// the stored report and whether it is new; uploading a report again
+    /// returns the one stored the first time.
+    pub async fn save(
+        &self,
+        upload: &CrashUpload,
+        raw: serde_json::Value,
+        size: usize,
+        organization_id: Option<Uuid>,
+        now: DateTime<Utc>,
+    ) -> (CrashReport, bool) {
+        let mut reports = self.reports.write().await;
+        let existing = reports.values()
+            .find(|report| (report.agent_id, report.report_id) == (upload.agent_id, upload.id));
+        if let Some(existing) = existing {
+            return (existing.clone(), false);
+        }
+
+        let report = CrashReport {
+            id: Uuid::new_v4(),
+            agent_id: upload.agent_id,
+            organization_id,
+            report_id: upload.id,
+            version: upload.version.clone(),
+            os: upload.os.clone(),
+            kind: upload.kind,
+            message: upload.message.clone(),
+            group_key: upload.group_key(),
+            report: DbJson(raw),
+            size: size as i64,
+            crashed_at: upload.crashed_at,
+            received_at: now,
+        };
+        reports.insert(report.id, report.clone());
+
+        // Drop the device's oldest reports beyond the retention count
+        let mut own: Vec<(DateTime<Utc>, Uuid)> = reports.values()
+            .filter(|stored| stored.agent_id == upload.agent_id)
+            .map(|stored| (stored.crashed_at, stored.id))
+            .collect();
+        own.sort_by(|a, b| b.cmp(a));
+        let stale: Vec<Uuid> = own.into_iter().skip(self.keep).map(|(_, id)| id).collect();
+        for id in &stale {
+            reports.remove(id);
+        }
+        drop(reports);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.insert_crash_report(&report).await {
+                warn!("Failed to store crash report {}: {}", report.id, e);
+            }
+            for id in stale {
+                if let Err(e) = db.delete_crash_report(id).await {
+                    warn!("Failed to drop crash report {}: {}", id, e);
+                }
+            }
+        }
+        self.note_crash(report.agent_id, report.crashed_at).await;
+        (report, true)
+    }
+
+    pub async fn get(&self, id: Uuid) -> Option<CrashReport> {
+        self.reports.read().await.get(&id).cloned()
+    }
+
+    /// Reports matching `filter`, newest first
+    pub async fn list(&self, filter: &CrashFilter) -> Vec<CrashReport> {
+        let mut reports: Vec<CrashReport> = self.reports.read().await.values()
+            .filter(|report| filter.matches(report))
+            .cloned()
+            .collect();
+        reports.sort_by(|a, b| b.crashed_at.cmp(&a.crashed_at).then(b.id.cmp(&a.id)));
+        reports
+    }
+
+    /// Reports matching `filter` grouped by their group key, the group that
+    /// crashed most recently first
+    pub async fn groups(&self, filter: &CrashFilter) -> Vec<CrashGroup> {
+        let mut groups: Vec<CrashGroup> = Vec::new();
+        let mut devices: HashMap<String, Vec<Uuid>> = HashMap::new();
+        // Newest first, so each group's first report is its latest
+        for report in self.list(filter).await {
+            let seen = devices.entry(report.group_key.clone()).or_default();
+            if !seen.contains(&report.agent_id) {
+                seen.push(report.agent_id);
+            }
+            match groups.iter_mut().find(|group| group.group_key == report.group_key) {
+                Some(group) => {
+                    group.count += 1;
+                    group.first_seen = report.crashed_at;
+                    if !group.versions.contains(&report.version) {
+                        group.versions.push(report.version);
+                    }
+                }
+                None => groups.push(CrashGroup {
+                    group_key: report.group_key,
+                    count: 1,
+                    devices: 0,
+                    versions: vec![report.version],
+                    first_seen: report.crashed_at,
+                    last_seen: report.crashed_at,
+                    latest_id: report.id,
+                }),
+            }
+        }
+        for group in &mut groups {
+            group.devices = devices.get(&group.group_key).map_or(0, Vec::len);
+        }
+        groups
+    }
+
+    /// Remember that `agent_id` crashed at `at`, as a heartbeat or report
+    /// said
+    pub async fn note_crash(&self, agent_id: Uuid, at: DateTime<Utc>) {
+        let mut last_crash = self.last_crash.write().await;
+        let latest = last_crash.entry(agent_id).or_insert(at);
+        *latest = (*latest).max(at);
+    }
+
+    /// How often `agent_id` crashed
+    pub async fn summary(&self, agent_id: Uuid) -> CrashSummary {
+        let reports = self.reports.read().await.values().filter(|report| report.agent_id == agent_id).count();
+        let last_crash_at = self.last_crash.read().await.get(&agent_id).copied();
+        CrashSummary { reports, last_crash_at }
+    }
+}
+
+/// Accept a crash report signed by the device it came from
+pub async fn api_upload_crash_report(State(app_state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+    if body.len() > MAX_REPORT_SIZE {
+        return error(StatusCode::PAYLOAD_TOO_LARGE, format!("Crash reports are limited to {} bytes", MAX_REPORT_SIZE));
+    }
+    let (upload, raw) = match (serde_json::from_slice::<CrashUpload>(&body), serde_json::from_slice::<serde_json::Value>(&body)) {
+        (Ok(upload), Ok(raw)) => (upload, raw),
+        (Err(e), _) | (_, Err(e)) => return error(StatusCode::BAD_REQUEST, format!("Invalid crash report: {}", e)),
+    };
+    if let Err(response) = app_state.enrollment.verify_signed_upload(&headers, upload.agent_id, &body, UploadKind::CrashReport).await {
+        return response;
+    }
+
+    let device_manager = &app_state.device_manager;
+    // An agent with a stale policy may still try; the policy wins
+    let allowed = device_manager.effective_policy(upload.agent_id).await
+        .and_then(|(policy, _)| policy.crash_reports_enabled)
+        .unwrap_or(true);
+    if !allowed {
+        return error(StatusCode::FORBIDDEN, "The device's policy does not allow crash reports");
+    }
+    let organization_id = device_manager.get_agent(upload.agent_id).await.and_then(|(agent, _)| agent.organization_id);
+    let (report, created) = device_manager.crash_reports.save(&upload, raw, body.len(), organization_id, Utc::now()).await;
+    if !created {
+        return Json(report).into_response();
+    }
+
+    info!("Stored crash report {} from {} in group {:?}", report.id, report.agent_id, report.group_key);
+    device_manager.record_audit(
+        AuditLog::new("device", "crash_report_uploaded")
+            .actor(report.agent_id.to_string())
+            .agent(report.agent_id)
+            .details(serde_json::json!({
+                "crash_report": report.id,
+                "kind": report.kind,
+                "version": report.version,
+                "group_key": report.group_key,
+            })),
+    ).await;
+    (StatusCode::CREATED, Json(report)).into_response()
+}
+
+/// Filters of `GET /api/crash-reports` and `GET /api/crash-reports/groups`
+#[derive(Debug, Deserialize)]
+pub struct CrashQuery {
+    pub device: Option<Uuid>,
+    pub group: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl CrashQuery {
+    fn filter(self, tenant: Tenant) -> CrashFilter {
+        CrashFilter {
+            tenant,
+            agent_id: self.device,
+            group_key: self.group,
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+/// Crash reports of the caller's organization, newest first
+pub async fn api_list_crash_reports(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<CrashQuery>,
+) -> Response {
+    let reports = app_state.device_manager.crash_reports.list(&query.filter(tenant)).await;
+    Json(serde_json::json!({ "crash_reports": reports })).into_response()
+}
+
+/// Crash reports of the caller's organization grouped by top frame
+pub async fn api_crash_report_groups(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<CrashQuery>,
+) -> Response {
+    let groups = app_state.device_manager.crash_reports.groups(&query.filter(tenant)).await;
+    Json(serde_json::json!({ "groups": groups })).into_response()
+}
+
+/// One crash report with everything the agent sent
+pub async fn api_get_crash_report(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(id): Path<Uuid>,
+) -> Response {
+    match app_state.device_manager.crash_reports.get(id).await {
+        Some(report) if tenant.includes(report.organization_id) => {
+            let contents = report.report.0.clone();
+            Json(serde_json::json!({ "crash_report": report, "report": contents })).into_response()
+        }
+        _ => error(StatusCode::NOT_FOUND, format!("Crash report not found: {}", id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn upload(agent_id: Uuid, top_frame: Option<&str>, crashed_at: DateTime<Utc>) -> CrashUpload {
+        CrashUpload {
+            agent_id,
+            id: Uuid::new_v4(),
+            version: "0.2.0".to_string(),
+            os: "windows".to_string(),
+            kind: CrashKind::Panic,
+            message: "index out of bounds\nat src/capture/dxgi.rs".to_string(),
+            top_frame: top_frame.map(str::to_string),
+            signal: None,
+            crashed_at,
+        }
+    }
+
+    #[test]
+    fn test_group_key_falls_back_to_signal_then_message() {
+        let now = Utc::now();
+        let agent_id = Uuid::new_v4();
+        assert_eq!(upload(agent_id, Some(" ghostlink_client::capture::dxgi::grab "), now).group_key(), "ghostlink_client::capture::dxgi::grab");
+        assert_eq!(upload(agent_id, None, now).group_key(), "index out of bounds");
+
+        let signal = CrashUpload { kind: CrashKind::Signal, signal: Some(11), message: String::new(), ..upload(agent_id, None, now) };
+        assert_eq!(signal.group_key(), "signal 11");
+        let long = upload(agent_id, Some(&"x".repeat(2000)), now);
+        assert_eq!(long.group_key().len(), MAX_GROUP_KEY_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn test_reports_are_stored_once_grouped_and_scoped() {
+        let mut store = CrashReportStore::new();
+        store.keep = 3;
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+        let (desk, laptop, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let start = Utc::now() - Duration::hours(1);
+        let frame = Some("ghostlink_client::capture::encoder::encode");
+
+        let first = upload(desk, frame, start);
+        let (stored, created) = store.save(&first, serde_json::json!({}), 100, Some(acme), start).await;
+        assert!(created);
+        // A retried upload is the same report
+        let (again, created) = store.save(&first, serde_json::json!({}), 100, Some(acme), start).await;
+        assert_eq!((again.id, created), (stored.id, false));
+
+        store.save(&upload(laptop, frame, start + Duration::minutes(1)), serde_json::json!({}), 100, Some(acme), start).await;
+        store.save(&upload(laptop, None, start + Duration::minutes(2)), serde_json::json!({}), 100, Some(acme), start).await;
+        store.save(&upload(other, frame, start + Duration::minutes(3)), serde_json::json!({}), 100, Some(globex), start).await;
+
+        let filter = CrashFilter { tenant: Tenant::Organization(Some(acme)), ..Default::default() };
+        assert_eq!(store.list(&filter).await.len(), 3);
+        let groups = store.groups(&filter).await;
+        assert_eq!(groups.iter().map(|group| (group.group_key.as_str(), group.count, group.devices)).collect::<Vec<_>>(), [
+            ("index out of bounds", 1, 1),
+            ("ghostlink_client::capture::encoder::encode", 2, 2),
+        ]);
+        assert_eq!((groups[1].first_seen, groups[1].last_seen), (start, start + Duration::minutes(1)));
+
+        // Heartbeats count as crashes too, and devices keep their newest reports
+        store.note_crash(desk, start + Duration::minutes(30)).await;
+        assert_eq!(store.summary(desk).await, CrashSummary { reports: 1, last_crash_at: Some(start + Duration::minutes(30)) });
+        for minute in 10..13 {
+            store.save(&upload(desk, frame, start + Duration::minutes(minute)), serde_json::json!({}), 100, Some(acme), start).await;
+        }
+        assert_eq!(store.summary(desk).await.reports, 3);
+        assert!(store.get(stored.id).await.is_none());
+    }
+}
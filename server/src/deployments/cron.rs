@@ -0,0 +1,168 @@
+//! Five-field cron expressions for recurring deployments
+//!
+//! `minute hour day-of-month month day-of-week`, each a `*`, a number, a
+//! range `a-b` or a list of those, optionally stepped with `/n`. Sunday is
+//! 0 or 7. As in cron, when both day fields are restricted a day matching
+//! either one fires. Times are UTC.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Timelike, Utc};
+use serde::{Serialize, Serializer};
+use std::str::FromStr;
+
+/// How far ahead a firing is looked for; `0 0 30 2 *` never fires
+const SEARCH_YEARS: i32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    /// As written, for display
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields were given; an unrestricted one does not widen the match
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+/// Bit set of the values a field allows
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid {} field '{}'", name, field);
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse::<u32>().map_err(|_| invalid())?,
+                    end.parse::<u32>().map_err(|_| invalid())?,
+                ),
+                // `5/15` runs from 5 to the end of the range
+                None => {
+                    let start = range.parse::<u32>().map_err(|_| invalid())?;
+                    (start, if part.contains('/') { max } else { start })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("{} field '{}' is outside {}-{}", name, field, min, max));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("Cron expression '{}' needs 5 fields: minute hour day month weekday", expression));
+        };
+        let mut weekday_bits = parse_field(weekdays, "weekday", 0, 7)?;
+        // 7 is another name for Sunday
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: parse_field(minutes, "minute", 0, 59)?,
+            hours: parse_field(hours, "hour", 0, 23)?,
+            days: parse_field(days, "day", 1, 31)?,
+            months: parse_field(months, "month", 1, 12)?,
+            weekdays: weekday_bits,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        })
+    }
+}
+
+impl Serialize for CronSchedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.expression)
+    }
+}
+
+fn allows(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+impl CronSchedule {
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = allows(self.days, date.day());
+        let weekday = allows(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            (true, false) => day,
+            (false, true) => weekday,
+            (false, false) => true,
+        }
+    }
+
+    /// First firing strictly after `after`, skipping whole months, days and
+    /// hours that cannot match
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let after = after.naive_utc();
+        let mut time = after.date().and_hms_opt(after.hour(), after.minute(), 0)? + Duration::minutes(1);
+        let limit = after + Duration::days(366 * SEARCH_YEARS as i64);
+        while time <= limit {
+            let date = time.date();
+            if !allows(self.months, date.month()) {
+                let (year, month) = if date.month() == 12 { (date.year() + 1, 1) } else { (date.year(), date.month() + 1) };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_time(NaiveTime::MIN);
+            } else if !self.day_matches(date) {
+                time = date.succ_opt()?.and_time(NaiveTime::MIN);
+            } else if !allows(self.hours, time.hour()) {
+                time = date.and_hms_opt(time.hour(), 0, 0)? + Duration::hours(1);
+            } else if !allows(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time.and_utc());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(expression: &str, after: &str) -> Option<DateTime<Utc>> {
+        expression.parse::<CronSchedule>().unwrap().next_after(at(after))
+    }
+
+    #[test]
+    fn test_cron_expressions_fire_when_expected() {
+        assert_eq!(next("*/15 * * * *", "2026-03-02T10:07:30Z"), Some(at("2026-03-02T10:15:00Z")));
+        assert_eq!(next("0 2 * * *", "2026-03-02T02:00:00Z"), Some(at("2026-03-03T02:00:00Z")));
+        // Weekdays at 18:30; 2026-03-06 is a Friday
+        assert_eq!(next("30 18 * * 1-5", "2026-03-06T19:00:00Z"), Some(at("2026-03-09T18:30:00Z")));
+        assert_eq!(next("0 9 * * 7", "2026-03-06T00:00:00Z"), Some(at("2026-03-08T09:00:00Z")));
+        // Either day field matching is enough when both are given
+        assert_eq!(next("0 0 1 * 1", "2026-03-02T00:00:00Z"), Some(at("2026-03-09T00:00:00Z")));
+        assert_eq!(next("0 0 29 2 *", "2026-03-01T00:00:00Z"), Some(at("2028-02-29T00:00:00Z")));
+        assert_eq!(next("0 0 30 2 *", "2026-03-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn test_malformed_cron_expressions_are_refused() {
+        for expression in ["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+            assert!(expression.parse::<CronSchedule>().is_err(), "{}", expression);
+        }
+        assert!("0,30 8-17/2 1-15 */3 mon".parse::<CronSchedule>().is_err());
+        assert!("0,30 8-17/2 1-15 */3 0-6".parse::<CronSchedule>().is_ok());
+    }
+}
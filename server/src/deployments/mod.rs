@@ -0,0 +1,1245 @@
+//! Running a tool or script on many devices at once
+//!
+//! A deployment sends `ExecuteTool` to the devices it targets, a few at a
+//! time, and collects the `ToolResult` each one sends back. Devices that are
+//! offline get the command when they next connect. Agents keep commands on
+//! disk until the server accepts their result, so an agent restarting in the
+//! middle of a run still reports it, and its tool policy decides whether the
+//! command runs at all; a refusal comes back as `blocked`.
+//!
+//! A deployment with `run_at` waits for that time. One with a cron
+//! expression stays scheduled and starts a new deployment of its own each
+//! time the expression fires, picking its targets again so devices added to
+//! a group since are included. Like support codes, deployments are kept in
+//! memory.
+
+use axum::{
+    extract::{ws::Message, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::{authz, jwt::{error, AuthUser}};
+use crate::device_groups::{self, DeviceFilter, DeviceListing, GroupError, GroupFilter};
+use crate::device_manager::DeviceManager;
+use crate::models::AuditLog;
+use crate::organizations::Tenant;
+use crate::toolbox::ToolboxManager;
+use crate::AppState;
+
+pub mod cron;
+
+use cron::CronSchedule;
+
+/// How often schedules are checked and silent devices given up on
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Devices running a deployment at once when the request does not say
+const DEFAULT_CONCURRENCY: usize = 10;
+
+/// Most devices a deployment may run on at once
+const MAX_CONCURRENCY: usize = 200;
+
+/// Seconds a run may take on a device when the request does not say
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+
+/// Longest run a request may allow
+const MAX_TIMEOUT_SECS: u64 = 4 * 60 * 60;
+
+/// Output kept per device; agents are asked not to send more
+pub const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// Largest inline script
+const MAX_SCRIPT_BYTES: usize = 256 * 1024;
+
+/// Seconds past its timeout a run holds its slot while the result is on its
+/// way; after that a silent device no longer holds up the rest
+const RESULT_GRACE_SECS: i64 = 120;
+
+/// Days a device has to pick up its command and report back
+const QUEUE_TTL_DAYS: i64 = 7;
+
+/// Days a finished deployment stays around to be looked at
+const RETENTION_DAYS: i64 = 30;
+
+/// How an inline script is run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpreter {
+    PowerShell,
+    Cmd,
+    Bash,
+    Sh,
+    Python,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InlineScript {
+    pub interpreter: Interpreter,
+    pub body: String,
+}
+
+/// Devices a deployment runs on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeploymentTarget {
+    Devices(Vec<Uuid>),
+    Group(Uuid),
+    /// Devices carrying all of these tags
+    Tags(Vec<String>),
+}
+
+/// What runs on each device
+#[derive(Debug, Clone, Serialize)]
+pub struct DeploymentJob {
+    pub tool_id: Option<Uuid>,
+    pub tool_name: Option<String>,
+    pub script: Option<InlineScript>,
+    pub parameters: HashMap<String, String>,
+    pub timeout_secs: u64,
+}
+
+/// When a deployment runs
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Now,
+    At(DateTime<Utc>),
+    Cron(CronSchedule),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeploymentStatus {
+    /// Waiting for `run_at`, or a cron schedule between runs
+    Scheduled,
+    Running,
+    /// Every device finished, one way or another
+    Completed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetStatus {
+    /// The device is offline; it gets the command when it connects
+    Queued,
+    /// Online and waiting for a slot under the concurrency limit
+    Pending,
+    /// Sent, waiting for the result
+    Running,
+    Succeeded,
+    Failed,
+    TimedOut,
+    /// The device's tool policy refused it
+    Blocked,
+    /// The device did not pick it up or report back in time
+    Expired,
+    Cancelled,
+}
+
+impl TargetStatus {
+    pub fn is_finished(self) -> bool {
+        !matches!(self, TargetStatus::Queued | TargetStatus::Pending | TargetStatus::Running)
+    }
+}
+
+/// One device's part in a deployment
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetRun {
+    pub agent_id: Uuid,
+    pub name: String,
+    /// Identifies the command on the agent and its result
+    pub execution_id: Uuid,
+    pub status: TargetStatus,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub exit_code: Option<i32>,
+    pub output: Option<String>,
+    /// Whether output was cut at `MAX_OUTPUT_BYTES`
+    pub truncated: bool,
+    pub duration_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Deployment {
+    pub id: Uuid,
+    pub created_by: Uuid,
+    pub requested_by: String,
+    pub organization_id: Option<Uuid>,
+    /// Devices the targets are picked from
+    #[serde(skip)]
+    tenant: Tenant,
+    pub job: DeploymentJob,
+    pub target: DeploymentTarget,
+    pub concurrency: usize,
+    pub status: DeploymentStatus,
+    pub cron: Option<CronSchedule>,
+    pub next_run_at: Option<DateTime<Utc>>,
+    /// The cron schedule this is a run of
+    pub schedule_id: Option<Uuid>,
+    /// Deployments a cron schedule started, oldest first
+    pub runs: Vec<Uuid>,
+    /// Why a scheduled deployment could not start
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub targets: Vec<TargetRun>,
+}
+
+/// Counts of targets for progress bars
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeploymentSummary {
+    pub total: usize,
+    /// Queued or pending
+    pub waiting: usize,
+    pub running: usize,
+    pub succeeded: usize,
+    /// Finished any other way
+    pub failed: usize,
+}
+
+/// A deployment as the API returns it
+#[derive(Debug, Serialize)]
+pub struct DeploymentView {
+    #[serde(flatten)]
+    pub deployment: Deployment,
+    pub summary: DeploymentSummary,
+}
+
+impl From<Deployment> for DeploymentView {
+    fn from(deployment: Deployment) -> Self {
+        let summary = deployment.summary();
+        Self { deployment, summary }
+    }
+}
+
+/// A validated request for a deployment
+#[derive(Debug, Clone)]
+pub struct NewDeployment {
+    pub created_by: Uuid,
+    pub requested_by: String,
+    pub tenant: Tenant,
+    pub job: DeploymentJob,
+    pub target: DeploymentTarget,
+    pub concurrency: usize,
+    pub schedule: Schedule,
+}
+
+/// What an agent reports a run ended with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolOutcome {
+    Succeeded,
+    Failed,
+    TimedOut,
+    Blocked,
+}
+
+impl From<ToolOutcome> for TargetStatus {
+    fn from(outcome: ToolOutcome) -> Self {
+        match outcome {
+            ToolOutcome::Succeeded => TargetStatus::Succeeded,
+            ToolOutcome::Failed => TargetStatus::Failed,
+            ToolOutcome::TimedOut => TargetStatus::TimedOut,
+            ToolOutcome::Blocked => TargetStatus::Blocked,
+        }
+    }
+}
+
+/// `ToolResult` from an agent
+#[derive(Debug, Clone, Deserialize)]
+pub struct ToolResult {
+    pub execution_id: Uuid,
+    pub status: ToolOutcome,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub output: String,
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default)]
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeploymentError {
+    Invalid(String),
+    ToolNotFound(Uuid),
+    DeviceNotFound(Uuid),
+    GroupNotFound,
+    /// The target matched no devices
+    NoTargets,
+    NotFound,
+    /// Completed or cancelled already
+    Finished,
+}
+
+impl std::fmt::Display for DeploymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeploymentError::Invalid(message) => write!(f, "{}", message),
+            DeploymentError::ToolNotFound(tool_id) => write!(f, "Tool {} not found", tool_id),
+            DeploymentError::DeviceNotFound(agent_id) => write!(f, "Device not found: {}", agent_id),
+            DeploymentError::GroupNotFound => write!(f, "Group not found"),
+            DeploymentError::NoTargets => write!(f, "No devices match the target"),
+            DeploymentError::NotFound => write!(f, "Deployment not found"),
+            DeploymentError::Finished => write!(f, "Deployment has already finished"),
+        }
+    }
+}
+
+impl IntoResponse for DeploymentError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            DeploymentError::Invalid(_) | DeploymentError::ToolNotFound(_) => StatusCode::BAD_REQUEST,
+            DeploymentError::DeviceNotFound(_) | DeploymentError::GroupNotFound | DeploymentError::NotFound => StatusCode::NOT_FOUND,
+            DeploymentError::NoTargets => StatusCode::UNPROCESSABLE_ENTITY,
+            DeploymentError::Finished => StatusCode::CONFLICT,
+        };
+        error(status, self.to_string())
+    }
+}
+
+/// Cut `output` to at most `max` bytes on a character boundary; whether it was cut
+fn truncate_output(output: &mut String, max: usize) -> bool {
+    if output.len() <= max {
+        return false;
+    }
+    let mut end = max;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    output.truncate(end);
+    true
+}
+
+impl Deployment {
+    fn new(request: NewDeployment, now: DateTime<Utc>) -> Self {
+        let (cron, next_run_at) = match request.schedule {
+            Schedule::Now => (None, None),
+            Schedule::At(run_at) => (None, Some(run_at).filter(|run_at| *run_at > now)),
+            Schedule::Cron(schedule) => {
+                let next_run_at = schedule.next_after(now);
+                (Some(schedule), next_run_at)
+            }
+        };
+        Self {
+            id: Uuid::new_v4(),
+            created_by: request.created_by,
+            requested_by: request.requested_by,
+            organization_id: request.tenant.organization_id(),
+            tenant: request.tenant,
+            job: request.job,
+            target: request.target,
+            concurrency: request.concurrency,
+            status: DeploymentStatus::Scheduled,
+            cron,
+            next_run_at,
+            schedule_id: None,
+            runs: Vec::new(),
+            error: None,
+            created_at: now,
+            started_at: None,
+            completed_at: None,
+            targets: Vec::new(),
+        }
+    }
+
+    /// A one-off deployment for this cron schedule's targets
+    fn next_run(&self) -> NewDeployment {
+        NewDeployment {
+            created_by: self.created_by,
+            requested_by: self.requested_by.clone(),
+            tenant: self.tenant,
+            job: self.job.clone(),
+            target: self.target.clone(),
+            concurrency: self.concurrency,
+            schedule: Schedule::Now,
+        }
+    }
+
+    /// Start on `devices`: online ones wait for a slot, offline ones for the device
+    fn begin(&mut self, devices: &[DeviceListing], now: DateTime<Utc>) {
+        self.status = DeploymentStatus::Running;
+        self.started_at = Some(now);
+        self.next_run_at = None;
+        self.targets = devices
+            .iter()
+            .map(|device| TargetRun {
+                agent_id: device.agent.id,
+                name: device.agent.name.clone(),
+                execution_id: Uuid::new_v4(),
+                status: if device.online { TargetStatus::Pending } else { TargetStatus::Queued },
+                sent_at: None,
+                completed_at: None,
+                exit_code: None,
+                output: None,
+                truncated: false,
+                duration_ms: None,
+                error: None,
+            })
+            .collect();
+        self.settle(now);
+    }
+
+    /// Complete the deployment once every target has finished
+    fn settle(&mut self, now: DateTime<Utc>) {
+        if self.status == DeploymentStatus::Running && self.targets.iter().all(|target| target.status.is_finished()) {
+            self.status = DeploymentStatus::Completed;
+            self.completed_at = Some(now);
+        }
+    }
+
+    /// Runs holding one of the deployment's slots
+    fn slots_in_use(&self, now: DateTime<Utc>) -> usize {
+        let held = Duration::seconds(self.job.timeout_secs as i64 + RESULT_GRACE_SECS);
+        self.targets
+            .iter()
+            .filter(|target| target.status == TargetStatus::Running && target.sent_at.is_some_and(|sent_at| sent_at + held > now))
+            .count()
+    }
+
+    fn summary(&self) -> DeploymentSummary {
+        let mut summary = DeploymentSummary { total: self.targets.len(), ..Default::default() };
+        for target in &self.targets {
+            match target.status {
+                TargetStatus::Queued | TargetStatus::Pending => summary.waiting += 1,
+                TargetStatus::Running => summary.running += 1,
+                TargetStatus::Succeeded => summary.succeeded += 1,
+                _ => summary.failed += 1,
+            }
+        }
+        summary
+    }
+
+    /// `ExecuteTool` for one of the targets
+    fn command(&self, target: &TargetRun) -> serde_json::Value {
+        serde_json::json!({
+            "type": "ExecuteTool",
+            "execution_id": target.execution_id,
+            "deployment_id": self.id,
+            "tool_id": self.job.tool_id,
+            "script": self.job.script,
+            "parameters": self.job.parameters,
+            "timeout_secs": self.job.timeout_secs,
+            "max_output_bytes": MAX_OUTPUT_BYTES,
+        })
+    }
+}
+
+/// Deployments and the state of each of their devices
+pub struct DeploymentManager {
+    deployments: RwLock<HashMap<Uuid, Deployment>>,
+}
+
+impl Default for DeploymentManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeploymentManager {
+    pub fn new() -> Self {
+        Self { deployments: RwLock::new(HashMap::new()) }
+    }
+
+    pub async fn get(&self, deployment_id: Uuid) -> Option<Deployment> {
+        self.deployments.read().await.get(&deployment_id).cloned()
+    }
+
+    async fn insert(&self, deployment: Deployment) {
+        self.deployments.write().await.insert(deployment.id, deployment);
+    }
+
+    /// Mark as many pending targets running as the concurrency limit allows,
+    /// with the command to send each
+    async fn start_next(&self, deployment_id: Uuid, now: DateTime<Utc>) -> Vec<(Uuid, serde_json::Value)> {
+        let mut deployments = self.deployments.write().await;
+        let Some(deployment) = deployments.get_mut(&deployment_id).filter(|d| d.status == DeploymentStatus::Running) else {
+            return Vec::new();
+        };
+        let free = deployment.concurrency.saturating_sub(deployment.slots_in_use(now));
+        let pending: Vec<usize> = deployment.targets
+            .iter()
+            .enumerate()
+            .filter(|(_, target)| target.status == TargetStatus::Pending)
+            .map(|(index, _)| index)
+            .take(free)
+            .collect();
+        pending
+            .into_iter()
+            .map(|index| {
+                let target = &mut deployment.targets[index];
+                target.status = TargetStatus::Running;
+                target.sent_at = Some(now);
+                let agent_id = target.agent_id;
+                (agent_id, deployment.command(&deployment.targets[index]))
+            })
+            .collect()
+    }
+
+    /// Put a target back in the queue after its device could not be reached
+    async fn requeue(&self, deployment_id: Uuid, agent_id: Uuid) {
+        if let Some(deployment) = self.deployments.write().await.get_mut(&deployment_id) {
+            for target in deployment.targets.iter_mut().filter(|target| target.agent_id == agent_id) {
+                if target.status == TargetStatus::Running {
+                    target.status = TargetStatus::Queued;
+                    target.sent_at = None;
+                }
+            }
+        }
+    }
+
+    /// Ready the targets waiting for `agent_id` to connect. Returns the
+    /// deployments with targets to start, and the commands already sent to
+    /// the agent that it has not reported on, to send again; agents ignore
+    /// commands they already have.
+    async fn agent_connected(&self, agent_id: Uuid) -> (Vec<Uuid>, Vec<serde_json::Value>) {
+        let mut ready = Vec::new();
+        let mut resend = Vec::new();
+        for deployment in self.deployments.write().await.values_mut() {
+            if deployment.status != DeploymentStatus::Running {
+                continue;
+            }
+            for target in deployment.targets.iter_mut().filter(|target| target.agent_id == agent_id) {
+                if target.status == TargetStatus::Queued {
+                    target.status = TargetStatus::Pending;
+                    ready.push(deployment.id);
+                }
+            }
+            let running = deployment.targets
+                .iter()
+                .filter(|target| target.agent_id == agent_id && target.status == TargetStatus::Running);
+            resend.extend(running.map(|target| deployment.command(target)));
+        }
+        (ready, resend)
+    }
+
+    /// Record `agent_id`'s result. Returns the deployment and the finished
+    /// target, or `None` for results that are unknown, repeated or from the
+    /// wrong agent.
+    async fn complete(&self, agent_id: Uuid, mut result: ToolResult, now: DateTime<Utc>) -> Option<(Uuid, TargetRun)> {
+        let mut deployments = self.deployments.write().await;
+        let deployment = deployments
+            .values_mut()
+            .find(|deployment| deployment.targets.iter().any(|target| target.execution_id == result.execution_id))?;
+        let target = deployment.targets.iter_mut().find(|target| target.execution_id == result.execution_id)?;
+        if target.agent_id != agent_id {
+            warn!("Agent {} reported on execution {} of agent {}", agent_id, result.execution_id, target.agent_id);
+            return None;
+        }
+        if target.status.is_finished() {
+            return None;
+        }
+
+        let truncated = truncate_output(&mut result.output, MAX_OUTPUT_BYTES) || result.truncated;
+        target.status = result.status.into();
+        target.completed_at = Some(now);
+        target.exit_code = result.exit_code;
+        target.output = Some(result.output);
+        target.truncated = truncated;
+        target.duration_ms = Some(result.duration_ms);
+        target.error = result.error;
+        let target = target.clone();
+        deployment.settle(now);
+        Some((deployment.id, target))
+    }
+
+    /// Stop a deployment: nothing more is sent, and a cron schedule stops
+    /// firing. Devices already running it still report back.
+    pub async fn cancel(&self, tenant: Tenant, deployment_id: Uuid, now: DateTime<Utc>) -> Result<Deployment, DeploymentError> {
+        let mut deployments = self.deployments.write().await;
+        let deployment = deployments
+            .get_mut(&deployment_id)
+            .filter(|deployment| tenant.includes(deployment.organization_id))
+            .ok_or(DeploymentError::NotFound)?;
+        if matches!(deployment.status, DeploymentStatus::Completed | DeploymentStatus::Cancelled) {
+            return Err(DeploymentError::Finished);
+        }
+        for target in &mut deployment.targets {
+            if matches!(target.status, TargetStatus::Queued | TargetStatus::Pending) {
+                target.status = TargetStatus::Cancelled;
+                target.completed_at = Some(now);
+            }
+        }
+        deployment.status = DeploymentStatus::Cancelled;
+        deployment.next_run_at = None;
+        deployment.completed_at = Some(now);
+        Ok(deployment.clone())
+    }
+
+    /// Deployments whose time has come. One-off deployments are marked
+    /// running, without targets yet; cron schedules move on to their next
+    /// firing and come back as they are, to start a run from.
+    async fn take_due(&self, now: DateTime<Utc>) -> Vec<Deployment> {
+        let mut due = Vec::new();
+        for deployment in self.deployments.write().await.values_mut() {
+            if deployment.status != DeploymentStatus::Scheduled || deployment.next_run_at.is_none_or(|at| at > now) {
+                continue;
+            }
+            match &deployment.cron {
+                Some(schedule) => {
+                    deployment.next_run_at = schedule.next_after(now);
+                    if deployment.next_run_at.is_none() {
+                        deployment.status = DeploymentStatus::Completed;
+                        deployment.completed_at = Some(now);
+                    }
+                }
+                None => {
+                    deployment.status = DeploymentStatus::Running;
+                    deployment.next_run_at = None;
+                }
+            }
+            due.push(deployment.clone());
+        }
+        due
+    }
+
+    /// Pick the targets of a scheduled deployment that just came due
+    async fn begin(&self, deployment_id: Uuid, devices: Result<Vec<DeviceListing>, DeploymentError>, now: DateTime<Utc>) {
+        if let Some(deployment) = self.deployments.write().await.get_mut(&deployment_id) {
+            match devices {
+                Ok(devices) => deployment.begin(&devices, now),
+                Err(e) => {
+                    deployment.status = DeploymentStatus::Completed;
+                    deployment.error = Some(e.to_string());
+                    deployment.completed_at = Some(now);
+                }
+            }
+        }
+    }
+
+    async fn add_run(&self, schedule_id: Uuid, run_id: Uuid) {
+        if let Some(schedule) = self.deployments.write().await.get_mut(&schedule_id) {
+            schedule.runs.push(run_id);
+        }
+    }
+
+    async fn running(&self) -> Vec<Uuid> {
+        self.deployments.read().await
+            .values()
+            .filter(|deployment| deployment.status == DeploymentStatus::Running)
+            .map(|deployment| deployment.id)
+            .collect()
+    }
+
+    /// Give up on devices that never reported back, and forget deployments
+    /// finished long ago. Returns the targets given up on.
+    async fn expire(&self, now: DateTime<Utc>) -> usize {
+        let mut deployments = self.deployments.write().await;
+        let mut expired = 0;
+        for deployment in deployments.values_mut() {
+            if deployment.started_at.is_none_or(|started_at| started_at + Duration::days(QUEUE_TTL_DAYS) > now) {
+                continue;
+            }
+            for target in deployment.targets.iter_mut().filter(|target| !target.status.is_finished()) {
+                target.status = TargetStatus::Expired;
+                target.completed_at = Some(now);
+                target.error = Some(format!("The device did not report back within {} days", QUEUE_TTL_DAYS));
+                expired += 1;
+            }
+            deployment.settle(now);
+        }
+        deployments.retain(|_, deployment| {
+            deployment.completed_at.is_none_or(|completed_at| completed_at + Duration::days(RETENTION_DAYS) > now)
+        });
+        expired
+    }
+}
+
+/// Devices of `tenant` that `target` names; named devices must all exist
+pub async fn resolve_targets(
+    device_manager: &DeviceManager,
+    tenant: Tenant,
+    target: &DeploymentTarget,
+) -> Result<Vec<DeviceListing>, DeploymentError> {
+    let mut devices = match target {
+        DeploymentTarget::Devices(agent_ids) => {
+            let mut devices = Vec::with_capacity(agent_ids.len());
+            let mut seen = HashSet::new();
+            for &agent_id in agent_ids.iter().filter(|agent_id| seen.insert(**agent_id)) {
+                match device_manager.get_agent(agent_id).await {
+                    Some((agent, online)) if tenant.includes(agent.organization_id) => {
+                        let access_paused_until = device_manager.access_paused_until(agent_id).await;
+                        devices.push(DeviceListing { agent, online, access_paused_until })
+                    }
+                    _ => return Err(DeploymentError::DeviceNotFound(agent_id)),
+                }
+            }
+            devices
+        }
+        DeploymentTarget::Group(group_id) => {
+            if device_manager.get_group(tenant, *group_id).await.is_none() {
+                return Err(DeploymentError::GroupNotFound);
+            }
+            let filter = DeviceFilter { group: Some(GroupFilter::Group(*group_id)), tenant, ..Default::default() };
+            device_manager.list_devices(&filter).await
+        }
+        DeploymentTarget::Tags(tags) => {
+            let tags = device_groups::normalize_tags(tags).map_err(|e| match e {
+                GroupError::Invalid(message) => DeploymentError::Invalid(message),
+                e => DeploymentError::Invalid(format!("Invalid tags: {:?}", e)),
+            })?;
+            if tags.is_empty() {
+                return Err(DeploymentError::Invalid("Name at least one tag".to_string()));
+            }
+            device_manager.list_devices(&DeviceFilter { tags, tenant, ..Default::default() }).await
+        }
+    };
+    if devices.is_empty() {
+        return Err(DeploymentError::NoTargets);
+    }
+    devices.sort_by(|a, b| a.agent.name.cmp(&b.agent.name).then(a.agent.id.cmp(&b.agent.id)));
+    Ok(devices)
+}
+
+/// Send the commands a deployment has slots for, queueing again for
+/// devices that went away in the meantime
+async fn dispatch(device_manager: &DeviceManager, deployment_id: Uuid, now: DateTime<Utc>) {
+    loop {
+        let started = device_manager.deployments.start_next(deployment_id, now).await;
+        let mut requeued = false;
+        for (agent_id, command) in started {
+            if let Err(e) = device_manager.send_to_device(agent_id, Message::Text(command.to_string())).await {
+                debug!("Queueing deployment {} for agent {}: {}", deployment_id, agent_id, e);
+                device_manager.deployments.requeue(deployment_id, agent_id).await;
+                requeued = true;
+            }
+        }
+        // Slots freed by unreachable devices go to the next ones
+        if !requeued {
+            return;
+        }
+    }
+}
+
+/// Create a deployment over `devices`, as picked by [`resolve_targets`], and
+/// start it unless it is scheduled
+pub async fn deploy(
+    device_manager: &DeviceManager,
+    request: NewDeployment,
+    devices: &[DeviceListing],
+    now: DateTime<Utc>,
+) -> Deployment {
+    let mut deployment = Deployment::new(request, now);
+    if deployment.cron.is_none() && deployment.next_run_at.is_none() {
+        deployment.begin(devices, now);
+    }
+    let deployment_id = deployment.id;
+    device_manager.deployments.insert(deployment.clone()).await;
+    dispatch(device_manager, deployment_id, now).await;
+    device_manager.deployments.get(deployment_id).await.unwrap_or(deployment)
+}
+
+/// Start what is due, fill slots of devices that went silent, and give up
+/// on devices that never came back
+pub async fn run_due(device_manager: &DeviceManager, now: DateTime<Utc>) {
+    let deployments = &device_manager.deployments;
+    for due in deployments.take_due(now).await {
+        let devices = resolve_targets(device_manager, due.tenant, &due.target).await;
+        if due.cron.is_none() {
+            deployments.begin(due.id, devices, now).await;
+            dispatch(device_manager, due.id, now).await;
+            continue;
+        }
+        match devices {
+            Ok(devices) => {
+                let mut run = Deployment::new(due.next_run(), now);
+                run.schedule_id = Some(due.id);
+                run.begin(&devices, now);
+                info!("Schedule {} started deployment {} on {} devices", due.id, run.id, run.targets.len());
+                let run_id = run.id;
+                deployments.insert(run).await;
+                deployments.add_run(due.id, run_id).await;
+                dispatch(device_manager, run_id, now).await;
+            }
+            Err(e) => warn!("Scheduled deployment {} skipped a run: {}", due.id, e),
+        }
+    }
+
+    for deployment_id in deployments.running().await {
+        dispatch(device_manager, deployment_id, now).await;
+    }
+    let expired = deployments.expire(now).await;
+    if expired > 0 {
+        info!("Gave up on {} devices that never reported a deployment", expired);
+    }
+}
+
+/// Hand a newly connected agent what was queued for it
+pub async fn deliver_queued(device_manager: &DeviceManager, agent_id: Uuid, now: DateTime<Utc>) {
+    let (ready, resend) = device_manager.deployments.agent_connected(agent_id).await;
+    for command in resend {
+        if let Err(e) = device_manager.send_to_device(agent_id, Message::Text(command.to_string())).await {
+            debug!("Failed to resend a deployment to agent {}: {}", agent_id, e);
+        }
+    }
+    for deployment_id in ready {
+        dispatch(device_manager, deployment_id, now).await;
+    }
+}
+
+/// Take a `ToolResult` from an agent and acknowledge it; the agent keeps
+/// sending it until it hears back
+pub async fn record_result(device_manager: &DeviceManager, agent_id: Uuid, message: serde_json::Value) {
+    let result: ToolResult = match serde_json::from_value(message) {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("Malformed tool result from agent {}: {}", agent_id, e);
+            return;
+        }
+    };
+    let execution_id = result.execution_id;
+    let now = Utc::now();
+    if let Some((deployment_id, target)) = device_manager.deployments.complete(agent_id, result, now).await {
+        info!("Deployment {} on agent {}: {:?}", deployment_id, agent_id, target.status);
+        device_manager.record_audit(
+            AuditLog::new("toolbox", "deployment_result")
+                .actor(agent_id.to_string())
+                .agent(agent_id)
+                .details(serde_json::json!({
+                    "deployment_id": deployment_id,
+                    "execution_id": execution_id,
+                    "status": target.status,
+                    "exit_code": target.exit_code,
+                    "duration_ms": target.duration_ms,
+                })),
+        ).await;
+        dispatch(device_manager, deployment_id, now).await;
+    }
+
+    let accepted = serde_json::json!({ "type": "ToolResultAccepted", "execution_id": execution_id });
+    if let Err(e) = device_manager.send_to_device(agent_id, Message::Text(accepted.to_string())).await {
+        debug!("Failed to acknowledge tool result to agent {}: {}", agent_id, e);
+    }
+}
+
+/// Body of a deploy request
+#[derive(Debug, Deserialize)]
+pub struct DeployRequest {
+    /// Toolbox tool to run; or give `script` and `interpreter`
+    #[serde(default)]
+    pub tool_id: Option<Uuid>,
+    #[serde(default)]
+    pub script: Option<String>,
+    #[serde(default)]
+    pub interpreter: Option<Interpreter>,
+    #[serde(default)]
+    pub parameters: HashMap<String, String>,
+    pub target: DeploymentTarget,
+    #[serde(default)]
+    pub run_at: Option<DateTime<Utc>>,
+    /// Five-field cron expression, in UTC
+    #[serde(default)]
+    pub cron: Option<String>,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+impl DeployRequest {
+    /// The tool or script to run, checked against what `tenant` may use
+    async fn job(&self, toolbox: &ToolboxManager, tenant: Tenant) -> Result<DeploymentJob, DeploymentError> {
+        let timeout_secs = self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
+        if !(1..=MAX_TIMEOUT_SECS).contains(&timeout_secs) {
+            return Err(DeploymentError::Invalid(format!("timeout_secs must be between 1 and {}", MAX_TIMEOUT_SECS)));
+        }
+        let (tool_id, tool_name, script) = match (self.tool_id, &self.script) {
+            (Some(_), Some(_)) => return Err(DeploymentError::Invalid("Give either tool_id or script, not both".to_string())),
+            (None, None) => return Err(DeploymentError::Invalid("Give a tool_id or a script to run".to_string())),
+            (Some(tool_id), None) => {
+                let tool = toolbox.get_tool(tool_id).await
+                    .filter(|tool| tool.visible_to(tenant))
+                    .ok_or(DeploymentError::ToolNotFound(tool_id))?;
+                let missing = tool.parameters.iter().find(|parameter| {
+                    parameter.required && parameter.default_value.is_none() && !self.parameters.contains_key(&parameter.name)
+                });
+                if let Some(parameter) = missing {
+                    return Err(DeploymentError::Invalid(format!("Missing parameter '{}'", parameter.name)));
+                }
+                (Some(tool_id), Some(tool.name), None)
+            }
+            (None, Some(body)) => {
+                let interpreter = self.interpreter
+                    .ok_or_else(|| DeploymentError::Invalid("An inline script needs an interpreter".to_string()))?;
+                if body.trim().is_empty() {
+                    return Err(DeploymentError::Invalid("Script is empty".to_string()));
+                }
+                if body.len() > MAX_SCRIPT_BYTES {
+                    return Err(DeploymentError::Invalid(format!("Scripts are at most {} bytes", MAX_SCRIPT_BYTES)));
+                }
+                (None, None, Some(InlineScript { interpreter, body: body.clone() }))
+            }
+        };
+        Ok(DeploymentJob { tool_id, tool_name, script, parameters: self.parameters.clone(), timeout_secs })
+    }
+
+    fn schedule(&self) -> Result<Schedule, DeploymentError> {
+        match (self.run_at, &self.cron) {
+            (Some(_), Some(_)) => Err(DeploymentError::Invalid("Give either run_at or cron, not both".to_string())),
+            (Some(run_at), None) => Ok(Schedule::At(run_at)),
+            (None, Some(expression)) => {
+                let schedule: CronSchedule = expression.parse().map_err(DeploymentError::Invalid)?;
+                if schedule.next_after(Utc::now()).is_none() {
+                    return Err(DeploymentError::Invalid(format!("Cron expression '{}' never fires", expression)));
+                }
+                Ok(Schedule::Cron(schedule))
+            }
+            (None, None) => Ok(Schedule::Now),
+        }
+    }
+
+    fn concurrency(&self) -> Result<usize, DeploymentError> {
+        let concurrency = self.concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+        if !(1..=MAX_CONCURRENCY).contains(&concurrency) {
+            return Err(DeploymentError::Invalid(format!("concurrency must be between 1 and {}", MAX_CONCURRENCY)));
+        }
+        Ok(concurrency)
+    }
+}
+
+/// Run a tool or script on a set of devices, now or on a schedule
+pub async fn api_deploy(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Json(request): Json<DeployRequest>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    let checked = async {
+        let job = request.job(&device_manager.toolbox_manager, tenant).await?;
+        let schedule = request.schedule()?;
+        let concurrency = request.concurrency()?;
+        let devices = resolve_targets(device_manager, tenant, &request.target).await?;
+        Ok::<_, DeploymentError>((job, schedule, concurrency, devices))
+    };
+    let (job, schedule, concurrency, devices) = match checked.await {
+        Ok(checked) => checked,
+        Err(e) => return e.into_response(),
+    };
+    // Running something is control of the device, whichever way it is asked for
+    for device in &devices {
+        if let Err(e) = authz::check_agent_permission(&app_state, &user, device.agent.id, true).await {
+            return e.into_response();
+        }
+    }
+
+    let new = NewDeployment {
+        created_by: user.user_id,
+        requested_by: user.email.clone(),
+        tenant,
+        job,
+        target: request.target,
+        concurrency,
+        schedule,
+    };
+    let deployment = deploy(device_manager, new, &devices, Utc::now()).await;
+    info!("Deployment {} created by {} for {} devices", deployment.id, user.email, devices.len());
+    device_manager.record_audit(
+        AuditLog::new("toolbox", "deployment_created")
+            .actor(user.user_id.to_string())
+            .request(&context)
+            .details(serde_json::json!({
+                "deployment_id": deployment.id,
+                "tool_id": deployment.job.tool_id,
+                "interpreter": deployment.job.script.as_ref().map(|script| script.interpreter),
+                "target": deployment.target,
+                "devices": devices.iter().map(|device| device.agent.id).collect::<Vec<_>>(),
+                "run_at": deployment.next_run_at,
+                "cron": deployment.cron,
+            })),
+    ).await;
+    (StatusCode::CREATED, Json(DeploymentView::from(deployment))).into_response()
+}
+
+/// A deployment with the status of each of its devices
+pub async fn api_get_deployment(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(deployment_id): Path<Uuid>,
+) -> Response {
+    match app_state.device_manager.deployments.get(deployment_id).await {
+        Some(deployment) if tenant.includes(deployment.organization_id) => Json(DeploymentView::from(deployment)).into_response(),
+        _ => DeploymentError::NotFound.into_response(),
+    }
+}
+
+/// Stop a deployment or cron schedule
+pub async fn api_cancel_deployment(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(deployment_id): Path<Uuid>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    match device_manager.deployments.cancel(tenant, deployment_id, Utc::now()).await {
+        Ok(deployment) => {
+            device_manager.record_audit(
+                AuditLog::new("toolbox", "deployment_cancelled")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "deployment_id": deployment_id })),
+            ).await;
+            Json(DeploymentView::from(deployment)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_manager::DeviceRegistration;
+    use crate::relay::outbound::{self, testing::texts, OutboundReceiver};
+
+    async fn register(device_manager: &DeviceManager, organization_id: Option<Uuid>, name: &str, agent_id: Uuid) -> OutboundReceiver {
+        let (tx, rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: Some(name.to_string()),
+            hostname: name.to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: Some(agent_id.to_string()),
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id,
+        };
+        device_manager.register_device(registration, tx).await.unwrap();
+        rx
+    }
+
+    fn script_deployment(tenant: Tenant, target: DeploymentTarget, concurrency: usize, schedule: Schedule) -> NewDeployment {
+        NewDeployment {
+            created_by: Uuid::new_v4(),
+            requested_by: "tech@example.com".to_string(),
+            tenant,
+            job: DeploymentJob {
+                tool_id: None,
+                tool_name: None,
+                script: Some(InlineScript { interpreter: Interpreter::PowerShell, body: "Restart-Service Spooler".to_string() }),
+                parameters: HashMap::new(),
+                timeout_secs: 60,
+            },
+            target,
+            concurrency,
+            schedule,
+        }
+    }
+
+    async fn start(device_manager: &DeviceManager, tenant: Tenant, target: DeploymentTarget, concurrency: usize) -> Deployment {
+        let devices = resolve_targets(device_manager, tenant, &target).await.unwrap();
+        deploy(device_manager, script_deployment(tenant, target, concurrency, Schedule::Now), &devices, Utc::now()).await
+    }
+
+    fn result(execution_id: Uuid, status: &str, exit_code: Option<i32>, output: &str) -> serde_json::Value {
+        serde_json::json!({
+            "type": "ToolResult",
+            "execution_id": execution_id,
+            "status": status,
+            "exit_code": exit_code,
+            "output": output,
+            "duration_ms": 1200,
+        })
+    }
+
+    fn execution_id(deployment: &Deployment, agent_id: Uuid) -> Uuid {
+        deployment.targets.iter().find(|target| target.agent_id == agent_id).unwrap().execution_id
+    }
+
+    #[tokio::test]
+    async fn test_targets_are_picked_by_device_group_and_tag() {
+        let device_manager = DeviceManager::new();
+        let (acme, other) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let tenant = Tenant::Organization(acme);
+        let (front_desk, back_office, laptop, foreign) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let _rx = (
+            register(&device_manager, acme, "front-desk", front_desk).await,
+            register(&device_manager, acme, "back-office", back_office).await,
+            register(&device_manager, acme, "laptop", laptop).await,
+            register(&device_manager, other, "foreign", foreign).await,
+        );
+        device_manager.disconnect_device(back_office).await;
+
+        let group = device_manager.create_group(acme, "Reception", None).await.unwrap();
+        for agent_id in [front_desk, back_office] {
+            device_manager.set_device_group(agent_id, Some(group.id)).await.unwrap();
+        }
+        device_manager.set_device_tags(front_desk, &["printer".to_string()]).await.unwrap();
+        device_manager.set_device_tags(laptop, &["printer".to_string(), "mobile".to_string()]).await.unwrap();
+        device_manager.set_device_tags(foreign, &["printer".to_string()]).await.unwrap();
+
+        let ids = |devices: Vec<DeviceListing>| devices.iter().map(|device| (device.agent.name.clone(), device.online)).collect::<Vec<_>>();
+        let by_group = resolve_targets(&device_manager, tenant, &DeploymentTarget::Group(group.id)).await.unwrap();
+        assert_eq!(ids(by_group), [("back-office".to_string(), false), ("front-desk".to_string(), true)]);
+        let by_tag = resolve_targets(&device_manager, tenant, &DeploymentTarget::Tags(vec![" Printer".to_string()])).await.unwrap();
+        assert_eq!(ids(by_tag), [("front-desk".to_string(), true), ("laptop".to_string(), true)]);
+        let by_id = resolve_targets(&device_manager, tenant, &DeploymentTarget::Devices(vec![laptop, laptop, front_desk])).await.unwrap();
+        assert_eq!(by_id.len(), 2);
+
+        // Other organizations' devices and groups are out of reach
+        let foreign_target = DeploymentTarget::Devices(vec![laptop, foreign]);
+        assert_eq!(resolve_targets(&device_manager, tenant, &foreign_target).await.unwrap_err(), DeploymentError::DeviceNotFound(foreign));
+        let other_tenant = Tenant::Organization(other);
+        assert_eq!(resolve_targets(&device_manager, other_tenant, &DeploymentTarget::Group(group.id)).await.unwrap_err(), DeploymentError::GroupNotFound);
+        let unmatched = DeploymentTarget::Tags(vec!["kiosk".to_string()]);
+        assert_eq!(resolve_targets(&device_manager, tenant, &unmatched).await.unwrap_err(), DeploymentError::NoTargets);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_keeps_to_the_concurrency_limit() {
+        let device_manager = DeviceManager::new();
+        let agents: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut receivers = Vec::new();
+        for (index, agent_id) in agents.iter().enumerate() {
+            receivers.push(register(&device_manager, None, &format!("desk-{}", index), *agent_id).await);
+        }
+
+        let deployment = start(&device_manager, Tenant::All, DeploymentTarget::Devices(agents.clone()), 2).await;
+        let statuses: Vec<_> = deployment.targets.iter().map(|target| target.status).collect();
+        assert_eq!(statuses, [TargetStatus::Running, TargetStatus::Running, TargetStatus::Pending]);
+        let sent = texts(&receivers[0]).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["type"], "ExecuteTool");
+        assert_eq!(sent[0]["deployment_id"], serde_json::json!(deployment.id));
+        assert_eq!(sent[0]["script"]["interpreter"], "powershell");
+        assert_eq!(sent[0]["max_output_bytes"], MAX_OUTPUT_BYTES);
+        assert!(texts(&receivers[2]).await.is_empty(), "the third device waits for a slot");
+
+        record_result(&device_manager, agents[0], result(execution_id(&deployment, agents[0]), "succeeded", Some(0), "ok")).await;
+        let acks = texts(&receivers[0]).await;
+        assert_eq!(acks, [serde_json::json!({ "type": "ToolResultAccepted", "execution_id": execution_id(&deployment, agents[0]) })]);
+        let sent = texts(&receivers[2]).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["execution_id"], serde_json::json!(execution_id(&deployment, agents[2])));
+    }
+
+    #[tokio::test]
+    async fn test_offline_devices_get_their_commands_on_connect() {
+        let device_manager = DeviceManager::new();
+        let (online, offline) = (Uuid::new_v4(), Uuid::new_v4());
+        let online_rx = register(&device_manager, None, "online", online).await;
+        drop(register(&device_manager, None, "offline", offline).await);
+        device_manager.disconnect_device(offline).await;
+
+        let deployment = start(&device_manager, Tenant::All, DeploymentTarget::Devices(vec![online, offline]), 10).await;
+        let status = |deployment: &Deployment, agent_id| deployment.targets.iter().find(|target| target.agent_id == agent_id).unwrap().status;
+        assert_eq!(status(&deployment, offline), TargetStatus::Queued);
+        assert_eq!(texts(&online_rx).await.len(), 1);
+
+        // Nothing is lost to the restart: the command comes on connect, and
+        // again on the next one while the result is outstanding
+        let offline_rx = register(&device_manager, None, "offline", offline).await;
+        deliver_queued(&device_manager, offline, Utc::now()).await;
+        let sent = texts(&offline_rx).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["execution_id"], serde_json::json!(execution_id(&deployment, offline)));
+        let deployment = device_manager.deployments.get(deployment.id).await.unwrap();
+        assert_eq!(status(&deployment, offline), TargetStatus::Running);
+
+        device_manager.disconnect_device(offline).await;
+        let offline_rx = register(&device_manager, None, "offline", offline).await;
+        deliver_queued(&device_manager, offline, Utc::now()).await;
+        assert_eq!(texts(&offline_rx).await[0]["execution_id"], sent[0]["execution_id"]);
+
+        // Devices that never come back are given up on
+        device_manager.disconnect_device(offline).await;
+        run_due(&device_manager, Utc::now() + Duration::days(QUEUE_TTL_DAYS)).await;
+        let deployment = device_manager.deployments.get(deployment.id).await.unwrap();
+        assert_eq!(status(&deployment, offline), TargetStatus::Expired);
+        assert_eq!(deployment.status, DeploymentStatus::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_results_are_aggregated() {
+        let device_manager = DeviceManager::new();
+        let agents: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let mut receivers = Vec::new();
+        for (index, agent_id) in agents.iter().enumerate() {
+            receivers.push(register(&device_manager, None, &format!("desk-{}", index), *agent_id).await);
+        }
+        let deployment = start(&device_manager, Tenant::All, DeploymentTarget::Devices(agents.clone()), 10).await;
+        let id = |index: usize| execution_id(&deployment, agents[index]);
+
+        let long_output = "é".repeat(MAX_OUTPUT_BYTES);
+        record_result(&device_manager, agents[0], result(id(0), "succeeded", Some(0), &long_output)).await;
+        record_result(&device_manager, agents[1], result(id(1), "failed", Some(1), "Access is denied")).await;
+        // Another agent cannot report for this one, and repeats change nothing
+        record_result(&device_manager, agents[3], result(id(2), "succeeded", Some(0), "")).await;
+        record_result(&device_manager, agents[1], result(id(1), "succeeded", Some(0), "")).await;
+        let summary = device_manager.deployments.get(deployment.id).await.unwrap().summary();
+        assert_eq!(summary, DeploymentSummary { total: 4, waiting: 0, running: 2, succeeded: 1, failed: 1 });
+
+        record_result(&device_manager, agents[2], result(id(2), "timed_out", None, "partial")).await;
+        let blocked = serde_json::json!({
+            "type": "ToolResult",
+            "execution_id": id(3),
+            "status": "blocked",
+            "error": "custom tools are not allowed on this device",
+        });
+        record_result(&device_manager, agents[3], blocked).await;
+
+        let deployment = device_manager.deployments.get(deployment.id).await.unwrap();
+        assert_eq!(deployment.status, DeploymentStatus::Completed);
+        assert_eq!(deployment.summary(), DeploymentSummary { total: 4, waiting: 0, running: 0, succeeded: 1, failed: 3 });
+        let statuses: Vec<_> = deployment.targets.iter().map(|target| target.status).collect();
+        assert_eq!(statuses, [TargetStatus::Succeeded, TargetStatus::Failed, TargetStatus::TimedOut, TargetStatus::Blocked]);
+        let first = &deployment.targets[0];
+        assert!(first.truncated);
+        assert!(first.output.as_ref().unwrap().len() <= MAX_OUTPUT_BYTES);
+        assert_eq!((first.exit_code, first.duration_ms), (Some(0), Some(1200)));
+        assert_eq!(deployment.targets[1].output.as_deref(), Some("Access is denied"));
+        assert_eq!(deployment.targets[3].error.as_deref(), Some("custom tools are not allowed on this device"));
+
+        // Every result is acknowledged, so agents stop sending them
+        let acks = texts(&receivers[1]).await.iter().filter(|text| text["type"] == "ToolResultAccepted").count();
+        assert_eq!(acks, 2);
+    }
+
+    #[tokio::test]
+    async fn test_schedules_start_runs_until_cancelled() {
+        let device_manager = DeviceManager::new();
+        let agent_id = Uuid::new_v4();
+        let rx = register(&device_manager, None, "front-desk", agent_id).await;
+        let now = Utc::now();
+        let target = DeploymentTarget::Devices(vec![agent_id]);
+        let devices = resolve_targets(&device_manager, Tenant::All, &target).await.unwrap();
+
+        let later = script_deployment(Tenant::All, target.clone(), 1, Schedule::At(now + Duration::hours(1)));
+        let later = deploy(&device_manager, later, &devices, now).await;
+        let hourly = script_deployment(Tenant::All, target, 1, Schedule::Cron("0 * * * *".parse().unwrap()));
+        let hourly = deploy(&device_manager, hourly, &devices, now).await;
+        assert_eq!((later.status, hourly.status), (DeploymentStatus::Scheduled, DeploymentStatus::Scheduled));
+        assert!(later.targets.is_empty() && texts(&rx).await.is_empty());
+
+        run_due(&device_manager, now + Duration::hours(1)).await;
+        let later = device_manager.deployments.get(later.id).await.unwrap();
+        assert_eq!(later.status, DeploymentStatus::Running);
+        let hourly = device_manager.deployments.get(hourly.id).await.unwrap();
+        assert_eq!(hourly.runs.len(), 1);
+        assert!(hourly.next_run_at.unwrap() > now + Duration::hours(1));
+        let run = device_manager.deployments.get(hourly.runs[0]).await.unwrap();
+        assert_eq!((run.schedule_id, run.status), (Some(hourly.id), DeploymentStatus::Running));
+        // Both went out, as the one-off and the run each have a slot of their own
+        assert_eq!(texts(&rx).await.len(), 2);
+
+        device_manager.deployments.cancel(Tenant::All, hourly.id, now).await.unwrap();
+        run_due(&device_manager, now + Duration::hours(3)).await;
+        assert_eq!(device_manager.deployments.get(hourly.id).await.unwrap().runs.len(), 1);
+        assert_eq!(device_manager.deployments.cancel(Tenant::All, hourly.id, now).await.unwrap_err(), DeploymentError::Finished);
+    }
+}
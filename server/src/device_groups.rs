@@ -0,0 +1,704 @@
+//! Device groups, tags, aliases and filtered device listing
+//!
+//! Each agent carries its group, tags and alias. The device manager mirrors them,
+//! along with the platform and organization, into a [`DeviceIndex`] so that
+//! listing a large fleet by tenant, group, tag or platform only visits the
+//! devices that match.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::auth::{authz, jwt::{error, AuthUser}};
+use crate::models::{Agent, AuditLog};
+use crate::organizations::{self, Tenant};
+use crate::AppState;
+
+/// Most tags a device may carry
+pub const MAX_TAGS: usize = 32;
+
+/// Longest tag, in characters
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// Longest group name, in characters
+const MAX_GROUP_NAME: usize = 255;
+
+/// Shortest and longest device alias, in characters
+const MIN_ALIAS_LENGTH: usize = 3;
+const MAX_ALIAS_LENGTH: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GroupError {
+    NotFound,
+    DeviceNotFound,
+    /// Another group already has this name
+    DuplicateName,
+    /// Another device already goes by this alias
+    DuplicateAlias,
+    Invalid(String),
+}
+
+impl IntoResponse for GroupError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            GroupError::NotFound => (StatusCode::NOT_FOUND, "Group not found".to_string()),
+            GroupError::DeviceNotFound => (StatusCode::NOT_FOUND, "Device not found".to_string()),
+            GroupError::DuplicateName => (StatusCode::CONFLICT, "A group with this name already exists".to_string()),
+            GroupError::DuplicateAlias => (StatusCode::CONFLICT, "Another device already has this alias".to_string()),
+            GroupError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        error(status, message)
+    }
+}
+
+/// Trimmed group name, or why it can't be used
+pub fn normalize_group_name(name: &str) -> Result<String, GroupError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(GroupError::Invalid("Group name is empty".to_string()));
+    }
+    if name.chars().count() > MAX_GROUP_NAME {
+        return Err(GroupError::Invalid(format!("Group name is longer than {} characters", MAX_GROUP_NAME)));
+    }
+    Ok(name.to_string())
+}
+
+/// Tags trimmed, lowercased, sorted and deduplicated, or why they can't be used
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, GroupError> {
+    let mut normalized = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            return Err(GroupError::Invalid("Tags must not be empty".to_string()));
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH || tag.contains(|c: char| c.is_control() || c == ',') {
+            return Err(GroupError::Invalid(format!(
+                "Invalid tag '{}': tags are at most {} characters, without commas",
+                tag, MAX_TAG_LENGTH
+            )));
+        }
+        normalized.push(tag);
+    }
+    normalized.sort();
+    normalized.dedup();
+    if normalized.len() > MAX_TAGS {
+        return Err(GroupError::Invalid(format!("A device can have at most {} tags", MAX_TAGS)));
+    }
+    Ok(normalized)
+}
+
+/// Alias trimmed and lowercased, or why it can't be used
+pub fn normalize_alias(alias: &str) -> Result<String, GroupError> {
+    let alias = alias.trim().to_lowercase();
+    let valid = (MIN_ALIAS_LENGTH..=MAX_ALIAS_LENGTH).contains(&alias.len())
+        && alias.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !alias.starts_with('-')
+        && !alias.ends_with('-');
+    if !valid {
+        return Err(GroupError::Invalid(format!(
+            "Invalid alias '{}': aliases are {} to {} letters, digits and inner dashes",
+            alias, MIN_ALIAS_LENGTH, MAX_ALIAS_LENGTH
+        )));
+    }
+    // Devices are looked up by ID or alias, so an alias must not read as an ID
+    if Uuid::parse_str(&alias).is_ok() {
+        return Err(GroupError::Invalid("An alias must not be a device ID".to_string()));
+    }
+    Ok(alias)
+}
+
+/// Which group a listing is limited to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupFilter {
+    Group(Uuid),
+    /// Devices in no group
+    Ungrouped,
+}
+
+impl std::str::FromStr for GroupFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") {
+            return Ok(GroupFilter::Ungrouped);
+        }
+        Uuid::parse_str(s)
+            .map(GroupFilter::Group)
+            .map_err(|_| format!("Invalid group '{}': expected a group ID or 'none'", s))
+    }
+}
+
+/// Criteria for listing devices; every criterion given must match
+#[derive(Debug, Default, Clone)]
+pub struct DeviceFilter {
+    pub group: Option<GroupFilter>,
+    /// Tags the device must all carry, normalized
+    pub tags: Vec<String>,
+    pub online: Option<bool>,
+    /// Platform, compared case-insensitively
+    pub platform: Option<String>,
+    /// Text to find in the name or hostname, case-insensitively
+    pub query: Option<String>,
+    /// Organization whose devices are listed
+    pub tenant: Tenant,
+}
+
+impl DeviceFilter {
+    /// Whether the filter narrows the listing through the index
+    fn is_indexed(&self) -> bool {
+        self.group.is_some() || !self.tags.is_empty() || self.platform.is_some() || !self.tenant.is_super_admin()
+    }
+
+    pub fn matches(&self, agent: &Agent, online: bool) -> bool {
+        let query = self.query.as_deref().map(str::to_lowercase);
+        self.tenant.includes(agent.organization_id)
+            && self.online.is_none_or(|wanted| wanted == online)
+            && self.group.is_none_or(|group| match group {
+                GroupFilter::Group(id) => agent.group_id == Some(id),
+                GroupFilter::Ungrouped => agent.group_id.is_none(),
+            })
+            && self.tags.iter().all(|tag| agent.tags.contains(tag))
+            && self.platform.as_ref().is_none_or(|platform| agent.platform.eq_ignore_ascii_case(platform))
+            && query.is_none_or(|query| {
+                agent.name.to_lowercase().contains(&query)
+                    || agent.hostname.as_ref().is_some_and(|hostname| hostname.to_lowercase().contains(&query))
+            })
+    }
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+    organization_id: Option<Uuid>,
+    group_id: Option<Uuid>,
+    tags: Vec<String>,
+    platform: String,
+}
+
+/// Known devices by organization, group, tag and platform
+#[derive(Debug, Default)]
+pub struct DeviceIndex {
+    entries: HashMap<Uuid, IndexEntry>,
+    /// Devices per organization, those in none under `None`
+    by_organization: HashMap<Option<Uuid>, HashSet<Uuid>>,
+    by_group: HashMap<Uuid, HashSet<Uuid>>,
+    by_tag: HashMap<String, HashSet<Uuid>>,
+    by_platform: HashMap<String, HashSet<Uuid>>,
+}
+
+/// Remove `id` from the set under `key`, dropping the set once it is empty
+fn unlink<K: std::hash::Hash + Eq>(map: &mut HashMap<K, HashSet<Uuid>>, key: &K, id: Uuid) {
+    if let Some(ids) = map.get_mut(key) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+impl DeviceIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index `agent`, replacing what was indexed for it before
+    pub fn insert(&mut self, agent: &Agent) {
+        self.remove(agent.id);
+        let entry = IndexEntry {
+            organization_id: agent.organization_id,
+            group_id: agent.group_id,
+            tags: agent.tags.clone(),
+            platform: agent.platform.to_lowercase(),
+        };
+        self.by_organization.entry(entry.organization_id).or_default().insert(agent.id);
+        if let Some(group_id) = entry.group_id {
+            self.by_group.entry(group_id).or_default().insert(agent.id);
+        }
+        for tag in &entry.tags {
+            self.by_tag.entry(tag.clone()).or_default().insert(agent.id);
+        }
+        self.by_platform.entry(entry.platform.clone()).or_default().insert(agent.id);
+        self.entries.insert(agent.id, entry);
+    }
+
+    pub fn remove(&mut self, agent_id: Uuid) {
+        let Some(entry) = self.entries.remove(&agent_id) else {
+            return;
+        };
+        unlink(&mut self.by_organization, &entry.organization_id, agent_id);
+        if let Some(group_id) = entry.group_id {
+            unlink(&mut self.by_group, &group_id, agent_id);
+        }
+        for tag in &entry.tags {
+            unlink(&mut self.by_tag, tag, agent_id);
+        }
+        unlink(&mut self.by_platform, &entry.platform, agent_id);
+    }
+
+    /// Number of devices in `organization_id`, or in no organization
+    pub fn organization_size(&self, organization_id: Option<Uuid>) -> usize {
+        self.by_organization.get(&organization_id).map_or(0, HashSet::len)
+    }
+
+    /// Devices in `group_id`
+    pub fn group_members(&self, group_id: Uuid) -> Vec<Uuid> {
+        self.by_group.get(&group_id).map(|ids| ids.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// Devices matching the tenant, group, tag and platform criteria of
+    /// `filter`, or `None` when it has none and every device is a candidate
+    pub fn candidates(&self, filter: &DeviceFilter) -> Option<HashSet<Uuid>> {
+        if !filter.is_indexed() {
+            return None;
+        }
+
+        let mut sets: Vec<&HashSet<Uuid>> = Vec::new();
+        let empty = HashSet::new();
+        let ungrouped: HashSet<Uuid>;
+        if let Tenant::Organization(organization_id) = filter.tenant {
+            sets.push(self.by_organization.get(&organization_id).unwrap_or(&empty));
+        }
+        match filter.group {
+            Some(GroupFilter::Group(group_id)) => sets.push(self.by_group.get(&group_id).unwrap_or(&empty)),
+            Some(GroupFilter::Ungrouped) => {
+                ungrouped = self.entries.iter()
+                    .filter(|(_, entry)| entry.group_id.is_none())
+                    .map(|(id, _)| *id)
+                    .collect();
+                sets.push(&ungrouped);
+            }
+            None => {}
+        }
+        for tag in &filter.tags {
+            sets.push(self.by_tag.get(tag).unwrap_or(&empty));
+        }
+        if let Some(platform) = &filter.platform {
+            sets.push(self.by_platform.get(&platform.to_lowercase()).unwrap_or(&empty));
+        }
+
+        // Intersect starting from the smallest set
+        sets.sort_by_key(|ids| ids.len());
+        let (smallest, rest) = sets.split_first()?;
+        Some(smallest.iter().filter(|id| rest.iter().all(|ids| ids.contains(id))).copied().collect())
+    }
+}
+
+/// Field devices are listed by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceSort {
+    #[default]
+    Name,
+    Hostname,
+    Platform,
+    LastSeen,
+    Status,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A listed device and whether it is connected
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceListing {
+    #[serde(flatten)]
+    pub agent: Agent,
+    pub online: bool,
+    /// The end user paused remote access until then; the device is
+    /// connected but refuses sessions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_paused_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Sort `devices` by `sort`, breaking ties by ID so pages are stable
+pub fn sort_devices(devices: &mut [DeviceListing], sort: DeviceSort, order: SortOrder) {
+    let key = |a: &DeviceListing, b: &DeviceListing| -> Ordering {
+        match sort {
+            DeviceSort::Name => a.agent.name.to_lowercase().cmp(&b.agent.name.to_lowercase()),
+            DeviceSort::Hostname => a.agent.hostname.as_ref().map(|h| h.to_lowercase())
+                .cmp(&b.agent.hostname.as_ref().map(|h| h.to_lowercase())),
+            DeviceSort::Platform => a.agent.platform.cmp(&b.agent.platform),
+            DeviceSort::LastSeen => a.agent.last_seen.cmp(&b.agent.last_seen),
+            // Online devices first
+            DeviceSort::Status => b.online.cmp(&a.online),
+        }
+    };
+    devices.sort_by(|a, b| {
+        let ordering = match order {
+            SortOrder::Asc => key(a, b),
+            SortOrder::Desc => key(b, a),
+        };
+        ordering.then(a.agent.id.cmp(&b.agent.id))
+    });
+}
+
+// ============================================================================
+// API Handlers
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct GroupRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateGroupRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// New description; an empty string clears it
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceGroupRequest {
+    /// Group to file the device under; null takes it out of its group
+    pub group_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceTagsRequest {
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceAliasRequest {
+    /// New alias, or null to remove it
+    pub alias: Option<String>,
+}
+
+/// Groups of the caller's organization with their device counts, by name
+pub async fn api_list_groups(State(app_state): State<AppState>, tenant: Tenant) -> Response {
+    let groups = app_state.device_manager.list_groups(tenant).await;
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(group, device_count)| {
+            let mut group = serde_json::json!(group);
+            group["device_count"] = device_count.into();
+            group
+        })
+        .collect();
+    Json(serde_json::json!({ "groups": groups })).into_response()
+}
+
+pub async fn api_create_group(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Json(request): Json<GroupRequest>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    match device_manager.create_group(tenant.organization_id(), &request.name, request.description).await {
+        Ok(group) => {
+            device_manager.record_audit(
+                AuditLog::new("device", "group_created")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "group_id": group.id, "name": group.name })),
+            ).await;
+            (StatusCode::CREATED, Json(group)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// A group and the IDs of its devices
+pub async fn api_get_group(State(app_state): State<AppState>, tenant: Tenant, Path(group_id): Path<String>) -> Response {
+    let group_id = match organizations::parse_id(&group_id, "group") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    match app_state.device_manager.get_group(tenant, group_id).await {
+        Some((group, devices)) => Json(serde_json::json!({ "group": group, "devices": devices })).into_response(),
+        None => GroupError::NotFound.into_response(),
+    }
+}
+
+pub async fn api_update_group(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(group_id): Path<String>,
+    Json(request): Json<UpdateGroupRequest>,
+) -> Response {
+    let group_id = match organizations::parse_id(&group_id, "group") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.update_group(tenant, group_id, request.name.as_deref(), request.description).await {
+        Ok(group) => {
+            device_manager.record_audit(
+                AuditLog::new("device", "group_updated")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "group_id": group.id, "name": group.name })),
+            ).await;
+            Json(group).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Delete a group; its devices are left ungrouped
+pub async fn api_delete_group(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(group_id): Path<String>,
+) -> Response {
+    let group_id = match organizations::parse_id(&group_id, "group") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.delete_group(tenant, group_id).await {
+        Ok(ungrouped) => {
+            device_manager.record_audit(
+                AuditLog::new("device", "group_deleted")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "group_id": group_id, "ungrouped_devices": ungrouped })),
+            ).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// File a device under a group, or take it out of its group
+pub async fn api_set_device_group(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+    Json(request): Json<DeviceGroupRequest>,
+) -> Response {
+    let agent_id = match organizations::parse_id(&agent_id, "agent") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = authz::check_agent_permission(&app_state, &user, agent_id, true).await {
+        return e.into_response();
+    }
+    match app_state.device_manager.set_device_group(agent_id, request.group_id).await {
+        Ok(agent) => Json(serde_json::json!({ "device": agent })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Replace a device's tags
+pub async fn api_set_device_tags(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+    Json(request): Json<DeviceTagsRequest>,
+) -> Response {
+    let agent_id = match organizations::parse_id(&agent_id, "agent") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = authz::check_agent_permission(&app_state, &user, agent_id, true).await {
+        return e.into_response();
+    }
+    match app_state.device_manager.set_device_tags(agent_id, &request.tags).await {
+        Ok(agent) => Json(serde_json::json!({ "device": agent })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Set or clear the alias technicians reach a device by
+pub async fn api_set_device_alias(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+    Json(request): Json<DeviceAliasRequest>,
+) -> Response {
+    let agent_id = match organizations::parse_id(&agent_id, "agent") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = authz::check_agent_permission(&app_state, &user, agent_id, true).await {
+        return e.into_response();
+    }
+    match app_state.device_manager.set_device_alias(agent_id, request.alias.as_deref()).await {
+        Ok(agent) => Json(serde_json::json!({ "device": agent })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixtures;
+
+    fn agent(name: &str, platform: &str, group_id: Option<Uuid>, tags: &[&str]) -> Agent {
+        let mut agent = fixtures::agent(name);
+        agent.platform = platform.to_string();
+        agent.group_id = group_id;
+        agent.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        agent
+    }
+
+    fn ids(candidates: Option<HashSet<Uuid>>) -> Vec<Uuid> {
+        let mut ids: Vec<Uuid> = candidates.expect("indexed filter").into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = ["Site:Berlin", " kiosk ", "kiosk", "POS"].map(String::from);
+        assert_eq!(normalize_tags(&tags).unwrap(), ["kiosk", "pos", "site:berlin"]);
+        assert!(normalize_tags(&["".to_string()]).is_err());
+        assert!(normalize_tags(&["a,b".to_string()]).is_err());
+        assert!(normalize_tags(&["x".repeat(MAX_TAG_LENGTH + 1)]).is_err());
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag-{}", i)).collect();
+        assert!(normalize_tags(&many).is_err());
+        assert_eq!(normalize_alias(" Front-Desk ").unwrap(), "front-desk");
+        for invalid in ["ab", "-desk", "desk-", "front desk", "kiosk_1", "0123456789abcdef0123456789abcdef"] {
+            assert!(normalize_alias(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!("none".parse::<GroupFilter>(), Ok(GroupFilter::Ungrouped));
+        assert!("berlin".parse::<GroupFilter>().is_err());
+    }
+
+    #[test]
+    fn test_index_follows_group_and_tag_changes() {
+        let (berlin, paris) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut index = DeviceIndex::new();
+        let mut kiosk = agent("kiosk-01", "windows", Some(berlin), &["kiosk", "pos"]);
+        let desk = agent("desk-01", "Linux", Some(berlin), &["pos"]);
+        index.insert(&kiosk);
+        index.insert(&desk);
+
+        let by_group = |group| DeviceFilter { group: Some(GroupFilter::Group(group)), ..Default::default() };
+        let mut both = vec![kiosk.id, desk.id];
+        both.sort();
+        assert_eq!(ids(index.candidates(&by_group(berlin))), both);
+
+        // Moving a device takes it out of the old group's set
+        kiosk.group_id = Some(paris);
+        kiosk.tags = vec!["kiosk".to_string()];
+        index.insert(&kiosk);
+        assert_eq!(ids(index.candidates(&by_group(berlin))), [desk.id]);
+        assert_eq!(ids(index.candidates(&by_group(paris))), [kiosk.id]);
+        assert_eq!(index.group_members(paris), [kiosk.id]);
+        let pos = DeviceFilter { tags: vec!["pos".to_string()], ..Default::default() };
+        assert_eq!(ids(index.candidates(&pos)), [desk.id]);
+
+        kiosk.group_id = None;
+        index.insert(&kiosk);
+        let ungrouped = DeviceFilter { group: Some(GroupFilter::Ungrouped), ..Default::default() };
+        assert_eq!(ids(index.candidates(&ungrouped)), [kiosk.id]);
+        assert!(!index.by_group.contains_key(&paris), "empty sets are dropped");
+
+        index.remove(desk.id);
+        assert!(ids(index.candidates(&by_group(berlin))).is_empty());
+        assert!(index.candidates(&DeviceFilter::default()).is_none());
+    }
+
+    #[test]
+    fn test_index_keeps_organizations_apart() {
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut index = DeviceIndex::new();
+        let mut own = agent("acme-desk", "windows", None, &["pos"]);
+        own.organization_id = Some(acme);
+        let mut other = agent("globex-desk", "windows", None, &["pos"]);
+        other.organization_id = Some(globex);
+        let unassigned = agent("spare", "windows", None, &["pos"]);
+        for device in [&own, &other, &unassigned] {
+            index.insert(device);
+        }
+
+        let in_acme = DeviceFilter { tenant: Tenant::Organization(Some(acme)), ..Default::default() };
+        assert_eq!(ids(index.candidates(&in_acme)), [own.id]);
+        assert!(in_acme.matches(&own, true));
+        assert!(!in_acme.matches(&other, true));
+        let pos_in_acme = DeviceFilter { tags: vec!["pos".to_string()], ..in_acme.clone() };
+        assert_eq!(ids(index.candidates(&pos_in_acme)), [own.id]);
+        let outside = DeviceFilter { tenant: Tenant::Organization(None), ..Default::default() };
+        assert_eq!(ids(index.candidates(&outside)), [unassigned.id]);
+        assert_eq!(index.organization_size(Some(globex)), 1);
+
+        // Moving a device between organizations moves it between their sets
+        other.organization_id = Some(acme);
+        index.insert(&other);
+        assert_eq!(index.organization_size(Some(globex)), 0);
+        assert_eq!(index.organization_size(Some(acme)), 2);
+        index.remove(own.id);
+        assert_eq!(ids(index.candidates(&in_acme)), [other.id]);
+    }
+
+    #[test]
+    fn test_filter_combinations() {
+        let site = Uuid::new_v4();
+        let mut index = DeviceIndex::new();
+        let devices = [
+            agent("Front Desk", "windows", Some(site), &["pos", "kiosk"]),
+            agent("back-office", "windows", Some(site), &["pos"]),
+            agent("build-box", "linux", None, &["pos"]),
+        ];
+        for device in &devices {
+            index.insert(device);
+        }
+
+        let matching = |filter: &DeviceFilter, online: bool| -> Vec<String> {
+            let candidates = index.candidates(filter);
+            let mut names: Vec<String> = devices
+                .iter()
+                .filter(|agent| candidates.as_ref().is_none_or(|ids| ids.contains(&agent.id)))
+                .filter(|agent| filter.matches(agent, online))
+                .map(|agent| agent.name.clone())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let filter = DeviceFilter {
+            group: Some(GroupFilter::Group(site)),
+            tags: vec!["pos".to_string()],
+            platform: Some("WINDOWS".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(matching(&filter, true), ["Front Desk", "back-office"]);
+
+        let filter = DeviceFilter { tags: vec!["pos".to_string(), "kiosk".to_string()], ..Default::default() };
+        assert_eq!(matching(&filter, true), ["Front Desk"]);
+
+        let filter = DeviceFilter { platform: Some("linux".to_string()), query: Some("BUILD".to_string()), ..Default::default() };
+        assert_eq!(matching(&filter, true), ["build-box"]);
+        let filter = DeviceFilter { online: Some(false), ..filter };
+        assert!(matching(&filter, true).is_empty());
+
+        let filter = DeviceFilter { query: Some("desk".to_string()), ..Default::default() };
+        assert_eq!(matching(&filter, false), ["Front Desk"]);
+        let filter = DeviceFilter { platform: Some("macos".to_string()), ..Default::default() };
+        assert!(matching(&filter, true).is_empty());
+    }
+
+    #[test]
+    fn test_sort_devices() {
+        let mut devices: Vec<DeviceListing> = [("b", true), ("A", false), ("c", true)]
+            .into_iter()
+            .map(|(name, online)| DeviceListing { agent: agent(name, "linux", None, &[]), online, access_paused_until: None })
+            .collect();
+        let names = |devices: &[DeviceListing]| devices.iter().map(|d| d.agent.name.clone()).collect::<Vec<_>>();
+
+        sort_devices(&mut devices, DeviceSort::Name, SortOrder::Asc);
+        assert_eq!(names(&devices), ["A", "b", "c"]);
+        sort_devices(&mut devices, DeviceSort::Name, SortOrder::Desc);
+        assert_eq!(names(&devices), ["c", "b", "A"]);
+        sort_devices(&mut devices, DeviceSort::Status, SortOrder::Asc);
+        assert_eq!(names(&devices)[2], "A");
+    }
+}
@@ -1,41 +1,157 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{RwLock, mpsc, oneshot};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tracing::{info, warn, debug};
-use axum::extract::ws::Message;
+use axum::extract::ws::{CloseFrame, Message};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 
-use crate::models::{Agent, Session, SessionType};
+use crate::audit::{self, AuditFile, AuditFilter};
+use crate::database::DatabaseService;
+use crate::device_groups::{self, DeviceFilter, DeviceIndex, DeviceListing, DeviceSort, GroupError, SortOrder};
+use crate::live_events::{EventHub, EventScope, LiveEvent, LiveSnapshot, Subscription};
+use crate::policies::{self, Consent, Policy, PolicyDocument, PolicyError, PolicyScope};
+use crate::models::{Agent, AuditLog, DeviceGroup, NetworkInterface, Organization, Session, SessionAuditLog, SessionCommand, SessionType};
+use crate::organizations::{self, OrganizationError, Tenant};
 use crate::toolbox::ToolboxManager;
 use crate::branding::BrandingManager;
 use crate::direct_connect::DirectConnectManager;
 use crate::vpn_integration::VpnManager;
 use crate::auth::oidc::OidcManager;
+use crate::auth::jwt::error;
+use crate::auth::access_grants::{AccessGrant, AccessGrants, GrantError};
+use crate::auth::session_tokens::{self, SessionTokens};
 use crate::pam::PamManager;
+use crate::recordings::{self, RecordingManager};
+use crate::device_records::DeviceRecords;
+use crate::session_notes::SessionNotes;
+use crate::crash_reports::CrashReportStore;
 use crate::terminal::TerminalManager;
+use crate::relay::{ConnectionType, MessagePriority, RelayManager, RelayNode, SessionRoute};
+use crate::relay::capabilities::{AgentCapabilities, AgentCapability, Unsupported};
+use crate::relay::chat::ChatTracker;
+use crate::relay::codecs::{self, ViewerCapabilities};
+use crate::relay::compression::{CompressionPolicy, COMPRESS_FRAMES_SETTING};
+use crate::relay::connection_broker::{BrokerError, BrokerPolicy, NodeHeartbeat, NodeRegistration};
+use crate::relay::control::{ControlOutcome, ControlTracker, SessionPolicy};
+use crate::relay::idle::{IdleAction, IdlePolicy, IdleTracker};
+use crate::relay::input_coalescer::{self, Flush, InputCoalescer};
+use crate::relay::liveness::{self, DeviceStatus, LivenessPolicy, LivenessTracker, Transition};
+use crate::relay::input_mode::{InputMode, INPUT_MODE_SETTING};
+use crate::relay::bandwidth::{self, BANDWIDTH_CAP_SETTING};
+use crate::relay::load_balancer::RouteHints;
+use crate::relay::outbound::{self, OutboundSender, OutboundStats};
+use crate::relay::protocol::{Envelope, MessageKind};
+use crate::relay::quality::QualityMonitor;
+use crate::relay::rendezvous::{self, NatType, RendezvousRegistry, Resolution};
+use crate::relay::udp_lane::UdpLane;
+use crate::rate_limit::{RateLimitPolicy, RateLimitStats, RateLimiter};
+use crate::releases::{ReleaseManager, UpdateReport};
+use crate::diagnostics::DiagnosticsStore;
+use crate::session_events::{self, SessionEvent, SessionEventKind};
+use crate::approvals::{self, Approval, ApprovalError, ApprovalTracker, HeldSession, Launch};
+use crate::session_transfers::{Transfer, TransferError, TransferState, TransferTracker};
+use crate::telemetry::Metrics;
+use crate::support_codes::{SupportCode, SupportCodeError, SupportCodeManager};
+use crate::deployments::DeploymentManager;
+use crate::webhooks::{self, WebhookEvent, WebhookManager, WebhookPolicy};
 
 /// Device connection state
 #[derive(Debug, Clone)]
 pub struct DeviceConnection {
     pub agent: Agent,
-    pub tx: mpsc::UnboundedSender<Message>,
+    pub tx: OutboundSender,
     pub last_ping: DateTime<Utc>,
     #[allow(dead_code)]
     pub connection_time: DateTime<Utc>,
     pub active_sessions: Vec<Uuid>,
+    /// Binary envelope version agreed at registration; `None` keeps to JSON
+    pub binary_protocol: Option<u8>,
+    /// The device agreed to receive compressed JSON
+    pub compression: bool,
+    /// The end user paused remote access until then
+    pub access_paused_until: Option<DateTime<Utc>>,
 }
 
 /// Session connection for web clients
 #[derive(Debug, Clone)]
 pub struct SessionConnection {
     pub session: Session,
-    pub tx: mpsc::UnboundedSender<Message>,
+    pub tx: OutboundSender,
+    /// Input waiting to be forwarded to the device
+    pub input: Arc<tokio::sync::Mutex<InputCoalescer>>,
+    /// Viewers watching alongside the technician, by user: a colleague
+    /// shadowing before a transfer, or the technician who handed over
+    pub observers: HashMap<Uuid, OutboundSender>,
     #[allow(dead_code)]
     pub connection_time: DateTime<Utc>,
 }
 
+/// Where a session's coalesced input goes
+struct InputTarget {
+    session_id: Uuid,
+    agent_id: Uuid,
+    tx: OutboundSender,
+    quality_monitor: Arc<QualityMonitor>,
+    broadcast_tx: mpsc::UnboundedSender<BroadcastMessage>,
+}
+
+impl InputTarget {
+    /// Send whatever input is waiting to the device
+    async fn flush(&self, input: &mut InputCoalescer) -> Result<(), String> {
+        let Some(batch) = input.flush() else {
+            return Ok(());
+        };
+        self.quality_monitor.record_input(self.session_id, input.counters()).await;
+
+        let envelope = Envelope::new(MessageKind::InputBatch, self.session_id, &batch);
+        self.tx.send_wait(Message::Binary(envelope.encode()), MessagePriority::High).await
+            .map_err(|e| format!("Failed to forward input to device: {}", e))?;
+
+        let _ = self.broadcast_tx.send(BroadcastMessage::InputEvent(self.agent_id, batch));
+        Ok(())
+    }
+}
+
+/// How long to wait for an agent to answer a registry command
+const REGISTRY_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait for an agent to answer a file browser request
+const FS_TIMEOUT_SECS: u64 = 30;
+
+/// Ended sessions kept in memory for the session API
+const ENDED_SESSION_HISTORY: usize = 1000;
+
+/// Heartbeat samples kept per device; an hour at the default 30s interval
+const METRICS_HISTORY: usize = 120;
+
+/// Commands kept in memory per session for the timeline
+const SESSION_COMMAND_HISTORY: usize = 500;
+
+/// Settings key of the viewer's cursor streaming toggle; absent means on
+pub const CURSOR_STREAMING_SETTING: &str = "cursor_streaming";
+
+/// Settings key of whether the agent previews the session's input instead
+/// of injecting it, as it last confirmed; absent means off
+pub const INPUT_PREVIEW_SETTING: &str = "input_preview";
+
+/// Timeline events kept in memory per session
+const SESSION_EVENT_HISTORY: usize = 2000;
+
+/// Metadata key of why the agent could not start a session, with its
+/// pre-flight report
+pub const SESSION_FAILURE_METADATA: &str = "failure";
+
+/// `session_audit_log` event types of command results and timeline events
+const COMMAND_EVENT: &str = "command_executed";
+const TIMELINE_EVENT: &str = "timeline_event";
+
+/// Where an agent's archive chunks go for one download
+type ArchiveSender = mpsc::Sender<Result<Vec<u8>, std::io::Error>>;
+
 /// Manages all device connections and sessions
 pub struct DeviceManager {
     /// Connected devices indexed by agent ID
@@ -44,6 +160,36 @@ pub struct DeviceManager {
     /// Active sessions indexed by session ID
     sessions: Arc<RwLock<HashMap<Uuid, SessionConnection>>>,
     
+    /// Agents that disconnected since the server started, indexed by agent ID
+    offline_agents: Arc<RwLock<HashMap<Uuid, Agent>>>,
+    
+    /// Organizations indexed by organization ID
+    organizations: Arc<RwLock<HashMap<Uuid, Organization>>>,
+
+    /// Device groups indexed by group ID
+    groups: Arc<RwLock<HashMap<Uuid, DeviceGroup>>>,
+
+    /// Client policies indexed by policy ID
+    policies: Arc<RwLock<HashMap<Uuid, Policy>>>,
+    
+    /// Connected and offline agents by organization, group, tag and platform
+    device_index: Arc<RwLock<DeviceIndex>>,
+    
+    /// Most recently ended sessions, oldest first
+    ended_sessions: Arc<RwLock<VecDeque<Session>>>,
+    
+    /// Recent heartbeat metrics per agent, oldest first
+    metrics: Arc<RwLock<HashMap<Uuid, VecDeque<MetricsSample>>>>,
+    
+    /// Commands run from session windows per session, oldest first
+    session_commands: Arc<RwLock<HashMap<Uuid, VecDeque<SessionCommand>>>>,
+    
+    /// Timeline events per session, in timeline order
+    session_events: Arc<RwLock<HashMap<Uuid, Vec<SessionEvent>>>>,
+    
+    /// Dashboard connections following device, session and relay node changes
+    pub live_events: Arc<EventHub>,
+    
     /// Channel for broadcasting messages between devices and sessions
     broadcast_tx: mpsc::UnboundedSender<BroadcastMessage>,
     #[allow(dead_code)]
@@ -69,6 +215,114 @@ pub struct DeviceManager {
     
     /// Terminal manager for web-based command execution
     pub terminal_manager: Arc<TerminalManager>,
+    
+    /// Latency probes and quality reports of active sessions
+    pub quality_monitor: Arc<QualityMonitor>,
+    
+    /// Receipt state of in-session chat
+    pub chat_tracker: Arc<ChatTracker>,
+    
+    /// Codes end users join ad-hoc sessions with
+    pub support_codes: Arc<SupportCodeManager>,
+    
+    /// Tools and scripts run across many devices
+    pub deployments: Arc<DeploymentManager>,
+    
+    /// Endpoints told about device, session and agent events
+    pub webhooks: Arc<WebhookManager>,
+    
+    /// Session recordings agents uploaded
+    pub recordings: Arc<RecordingManager>,
+    
+    /// Notes, custom fields and inventory of devices
+    pub device_records: Arc<DeviceRecords>,
+    
+    /// Session notes and the templates organizations keep for them
+    pub session_notes: Arc<SessionNotes>,
+    
+    /// Crash reports agents uploaded
+    pub crash_reports: Arc<CrashReportStore>,
+    
+    /// Scoped tokens viewers attach to sessions with
+    pub session_tokens: Arc<SessionTokens>,
+    
+    /// Temporary access of guests to single devices
+    pub access_grants: Arc<AccessGrants>,
+    
+    /// Which session of each device may inject input
+    pub control: Arc<ControlTracker>,
+    
+    /// Sessions being handed from one technician to another
+    pub transfers: Arc<TransferTracker>,
+    
+    /// Backstage sessions waiting for someone to approve them
+    pub approvals: Arc<ApprovalTracker>,
+    
+    /// Per-device session limits and takeover behavior
+    session_policy: SessionPolicy,
+    
+    /// How long pointer motion is held to coalesce before it is forwarded
+    input_window: std::time::Duration,
+    
+    /// When JSON sent to devices that agreed to it is compressed
+    compression_policy: CompressionPolicy,
+    
+    /// Last technician activity of every active session
+    pub idle_tracker: Arc<IdleTracker>,
+    
+    /// How long sessions may stay idle
+    idle_policy: IdlePolicy,
+    
+    /// Seen and announced status of every device
+    pub liveness: Arc<LivenessTracker>,
+    
+    /// When silent devices count as degraded or offline
+    liveness_policy: LivenessPolicy,
+    
+    /// Agent builds published for auto-update
+    pub release_manager: Arc<ReleaseManager>,
+    
+    /// Diagnostics bundles uploaded by agents
+    pub diagnostics: Arc<DiagnosticsStore>,
+    
+    /// Relay nodes and the routes of sessions placed on them
+    pub relay_manager: Arc<RelayManager>,
+    
+    /// Where each connected device can currently be reached
+    pub rendezvous: Arc<RendezvousRegistry>,
+    
+    /// UDP port agents of hybrid sessions stream frames to, when enabled
+    pub udp_lane: Option<Arc<UdpLane>>,
+    
+    /// Login attempt buckets, lockouts and WebSocket slots
+    pub rate_limiter: Arc<RateLimiter>,
+    
+    /// Relay counters exported on `/metrics`
+    pub telemetry: Arc<Metrics>,
+    
+    /// Archive downloads awaiting chunks from agents, indexed by request ID
+    archive_streams: Arc<RwLock<HashMap<Uuid, ArchiveSender>>>,
+    
+    /// Registry commands awaiting a result from agents, indexed by request ID
+    registry_requests: Arc<RwLock<HashMap<Uuid, oneshot::Sender<serde_json::Value>>>>,
+    
+    /// File browser requests awaiting an `FsResult` from agents, indexed by request ID
+    fs_requests: Arc<RwLock<HashMap<Uuid, oneshot::Sender<serde_json::Value>>>>,
+    
+    /// Files the relay is uploading for a viewer's drop, indexed by transfer ID
+    drop_transfers: Arc<RwLock<HashMap<Uuid, DropRoute>>>,
+    
+    /// Database that agents, sessions and audit entries are written through to
+    db: Option<Arc<DatabaseService>>,
+
+    /// Where the audit trail is kept when there is no database
+    audit_file: Option<Arc<AuditFile>>,
+}
+
+/// Where the agent's answers about a dropped file go
+struct DropRoute {
+    session_id: Uuid,
+    replies: mpsc::Sender<serde_json::Value>,
 }
 
 /// Messages that can be broadcast between components.
@@ -94,14 +348,170 @@ pub struct DeviceRegistration {
     pub version: String,
     pub public_key: Option<String>,
     pub agent_id: Option<String>,
+    /// Adapters the agent reported, kept for Wake-on-LAN
+    #[serde(default)]
+    pub interfaces: Vec<NetworkInterface>,
+    /// What the agent can do; sessions it cannot serve are refused
+    #[serde(default)]
+    pub capabilities: AgentCapabilities,
+    /// Organization the agent enrolled into; set by the relay, never by the agent
+    #[serde(skip)]
+    pub organization_id: Option<Uuid>,
 }
 
 /// Session creation request
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct SessionRequest {
     pub agent_id: Uuid,
     pub session_type: SessionType,
     pub user_id: Uuid,
+    /// What the viewer decodes, handed to the agent with the session request
+    #[serde(default)]
+    pub capabilities: Option<ViewerCapabilities>,
+    /// Bandwidth cap in kbit/s; the policy ceiling applies either way
+    #[serde(default)]
+    pub bandwidth_kbps: Option<u32>,
+    /// The agent may compress frames while the link is the bottleneck
+    #[serde(default)]
+    pub compress_frames: bool,
+    /// Ticket in the organization's PSA the session is for
+    #[serde(default)]
+    pub ticket_ref: Option<String>,
+}
+
+/// Why a session was not created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionCreateError {
+    /// The device is unknown or not connected
+    Offline(Uuid),
+    /// Policy or the session limit does not allow it
+    Refused(String),
+    /// The agent lacks something the session needs
+    Unsupported(Unsupported),
+    /// The end user paused remote access until then
+    Paused(Uuid, DateTime<Utc>),
+}
+
+impl std::fmt::Display for SessionCreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionCreateError::Offline(agent_id) => write!(f, "Device not found or offline: {}", agent_id),
+            SessionCreateError::Refused(reason) => f.write_str(reason),
+            SessionCreateError::Unsupported(unsupported) => unsupported.fmt(f),
+            SessionCreateError::Paused(agent_id, until) => {
+                write!(f, "The user of device {} paused remote access until {}", agent_id, until.format("%Y-%m-%d %H:%M UTC"))
+            }
+        }
+    }
+}
+
+impl IntoResponse for SessionCreateError {
+    fn into_response(self) -> Response {
+        match self {
+            SessionCreateError::Unsupported(unsupported) => unsupported.into_response(),
+            paused @ SessionCreateError::Paused(..) => error(StatusCode::CONFLICT, paused.to_string()),
+            other => error(StatusCode::BAD_REQUEST, other.to_string()),
+        }
+    }
+}
+
+/// Resource usage an agent reports with each heartbeat
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceMetrics {
+    /// CPU usage across all cores, in percent
+    pub cpu_usage: f32,
+    pub memory_used: u64,
+    pub memory_total: u64,
+    /// Bytes used on the disk holding the system root
+    pub disk_used: u64,
+    pub disk_total: u64,
+    /// 1, 5 and 15 minute load averages; Windows agents do not report them
+    pub load_average: Option<[f64; 3]>,
+    pub active_sessions: u32,
+    /// Outcome of the agent's last self-update, while there is one to report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update: Option<UpdateReport>,
+    /// The agent crashed since its last heartbeat
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub crashed: bool,
+    /// What capture and recording take against the agent's limits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceUsage>,
+}
+
+/// Capture and recording load of an agent against its CPU and memory limits
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceUsage {
+    /// Capture and encode time, in percent of one core
+    pub cpu_percent: f32,
+    pub cpu_budget_percent: Option<u32>,
+    /// Bytes held by frame and recording buffers
+    pub memory_used: u64,
+    pub memory_limit: Option<u64>,
+    /// Frames delayed to stay within the CPU budget
+    pub frames_throttled: u64,
+    /// Queued frames dropped to stay under the memory limit
+    pub frames_shed: u64,
+}
+
+/// Heartbeat metrics stamped with the time the relay received them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsSample {
+    pub recorded_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub metrics: DeviceMetrics,
+}
+
+/// Criteria for listing active and ended sessions
+#[derive(Debug, Default, Clone)]
+pub struct SessionFilter {
+    pub agent_id: Option<Uuid>,
+    /// Session status, compared case-insensitively
+    pub status: Option<String>,
+    /// Only sessions started at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Organization whose sessions are listed
+    pub tenant: Tenant,
+}
+
+impl SessionFilter {
+    fn matches(&self, session: &Session) -> bool {
+        self.tenant.includes(session.organization_id)
+            && self.agent_id.is_none_or(|agent_id| session.agent_id == agent_id)
+            && self.status.as_ref().is_none_or(|status| session.status.eq_ignore_ascii_case(status))
+            && self.since.is_none_or(|since| session.started_at.is_some_and(|started| started >= since))
+    }
+}
+
+/// `ControlChanged` frame telling viewers which session holds input control
+fn control_changed(agent_id: Uuid, previous: Option<Uuid>, holder: Option<Uuid>) -> serde_json::Value {
+    serde_json::json!({
+        "type": "ControlChanged",
+        "agent_id": agent_id,
+        "holder": holder,
+        "previous": previous,
+    })
+}
+
+/// `ViewerRole` frame telling a viewer whether it runs the session or only
+/// watches it alongside the technician
+fn viewer_role(operator: bool) -> serde_json::Value {
+    serde_json::json!({
+        "type": "ViewerRole",
+        "role": if operator { "operator" } else { "observer" },
+    })
+}
+
+/// Mark a session as ended now
+fn mark_ended(session: &mut Session) {
+    let now = Utc::now();
+    session.status = "ended".to_string();
+    session.ended_at = Some(now);
+    session.duration_seconds = session.started_at
+        .map(|started| (now - started).num_seconds().max(0) as i32);
+    session.updated_at = now;
 }
 
 impl DeviceManager {
@@ -111,6 +521,16 @@ impl DeviceManager {
         Self {
             devices: Arc::new(RwLock::new(HashMap::new())),
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            offline_agents: Arc::new(RwLock::new(HashMap::new())),
+            organizations: Arc::new(RwLock::new(HashMap::new())),
+            groups: Arc::new(RwLock::new(HashMap::new())),
+            policies: Arc::new(RwLock::new(HashMap::new())),
+            device_index: Arc::new(RwLock::new(DeviceIndex::new())),
+            ended_sessions: Arc::new(RwLock::new(VecDeque::new())),
+            metrics: Arc::new(RwLock::new(HashMap::new())),
+            session_commands: Arc::new(RwLock::new(HashMap::new())),
+            session_events: Arc::new(RwLock::new(HashMap::new())),
+            live_events: Arc::new(EventHub::new()),
             broadcast_tx,
             broadcast_rx: Arc::new(RwLock::new(broadcast_rx)),
             toolbox_manager: Arc::new(ToolboxManager::new(std::path::PathBuf::from("./data/toolbox"))),
@@ -120,8 +540,155 @@ impl DeviceManager {
             oidc_manager: Arc::new(OidcManager::new()),
             pam_manager: Arc::new(PamManager::new()),
             terminal_manager: Arc::new(TerminalManager::new()),
+            quality_monitor: Arc::new(QualityMonitor::new()),
+            chat_tracker: Arc::new(ChatTracker::new()),
+            support_codes: Arc::new(SupportCodeManager::new()),
+            deployments: Arc::new(DeploymentManager::new()),
+            webhooks: Arc::new(WebhookManager::new(WebhookPolicy::default())),
+            recordings: Arc::new(RecordingManager::new(Arc::new(
+                recordings::storage::LocalStore::new(std::path::PathBuf::from("./data/recordings")),
+            ))),
+            device_records: Arc::new(DeviceRecords::new()),
+            session_notes: Arc::new(SessionNotes::new()),
+            crash_reports: Arc::new(CrashReportStore::new()),
+            // Replaced by the JWT-derived key in `main`; tokens from a random
+            // one die with the process
+            session_tokens: Arc::new(SessionTokens::new(&Uuid::new_v4().to_string())),
+            access_grants: Arc::new(AccessGrants::new(&Uuid::new_v4().to_string())),
+            control: Arc::new(ControlTracker::new()),
+            transfers: Arc::new(TransferTracker::new()),
+            approvals: Arc::new(ApprovalTracker::new()),
+            session_policy: SessionPolicy::default(),
+            input_window: input_coalescer::DEFAULT_WINDOW,
+            compression_policy: CompressionPolicy::default(),
+            idle_tracker: Arc::new(IdleTracker::new()),
+            idle_policy: IdlePolicy::default(),
+            liveness: Arc::new(LivenessTracker::new()),
+            liveness_policy: LivenessPolicy::default(),
+            release_manager: Arc::new(ReleaseManager::new(std::path::PathBuf::from("./data/releases"))),
+            diagnostics: Arc::new(DiagnosticsStore::new(std::path::PathBuf::from("./data/diagnostics"))),
+            relay_manager: Arc::new(RelayManager::new()),
+            rendezvous: Arc::new(RendezvousRegistry::new()),
+            udp_lane: None,
+            rate_limiter: Arc::new(RateLimiter::new(RateLimitPolicy::default())),
+            telemetry: Arc::new(Metrics::new()),
+            archive_streams: Arc::new(RwLock::new(HashMap::new())),
+            registry_requests: Arc::new(RwLock::new(HashMap::new())),
+            fs_requests: Arc::new(RwLock::new(HashMap::new())),
+            drop_transfers: Arc::new(RwLock::new(HashMap::new())),
+            db: None,
+            audit_file: None,
         }
     }
+
+    /// Write agents, sessions and audit entries through to `db`
+    pub fn with_database(mut self, db: Arc<DatabaseService>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Keep the audit trail in `file` while no database is configured
+    pub fn with_audit_file(mut self, file: Arc<AuditFile>) -> Self {
+        self.audit_file = Some(file);
+        self
+    }
+
+    /// Limit sessions per device and settle takeovers according to `policy`
+    pub fn with_session_policy(mut self, policy: SessionPolicy) -> Self {
+        self.session_policy = policy;
+        self
+    }
+
+    /// Hold pointer motion for `window` to coalesce it before forwarding;
+    /// zero forwards every batch the viewer sends at once
+    pub fn with_input_window(mut self, window: std::time::Duration) -> Self {
+        self.input_window = window;
+        self
+    }
+
+    /// Compress JSON sent to devices as `policy` says
+    pub fn with_compression(mut self, policy: CompressionPolicy) -> Self {
+        self.compression_policy = policy;
+        self
+    }
+
+    /// End sessions idle for longer than `policy` allows
+    pub fn with_idle_policy(mut self, policy: IdlePolicy) -> Self {
+        self.idle_policy = policy;
+        self
+    }
+
+    /// Judge device heartbeats by `policy`
+    pub fn with_liveness_policy(mut self, policy: LivenessPolicy) -> Self {
+        self.liveness_policy = policy;
+        self
+    }
+
+    /// Broker sessions onto relay nodes according to `policy`
+    pub fn with_broker_policy(mut self, policy: BrokerPolicy) -> Self {
+        self.relay_manager = Arc::new(RelayManager::with_policy(policy));
+        self
+    }
+
+    /// Offer hybrid sessions a UDP lane for their frames
+    pub fn with_udp_lane(mut self, lane: Arc<UdpLane>) -> Self {
+        self.udp_lane = Some(lane);
+        self
+    }
+
+    /// Throttle logins and WebSocket connections according to `policy`
+    pub fn with_rate_limits(mut self, policy: RateLimitPolicy) -> Self {
+        self.rate_limiter = Arc::new(RateLimiter::new(policy));
+        self
+    }
+
+    /// Sign session tokens with a key derived from `secret`
+    pub fn with_session_token_secret(mut self, secret: &str) -> Self {
+        self.session_tokens = Arc::new(SessionTokens::new(secret));
+        self
+    }
+
+    /// Sign access grant links with a key derived from `secret`
+    pub fn with_access_grant_secret(mut self, secret: &str) -> Self {
+        self.access_grants = Arc::new(AccessGrants::new(secret));
+        self
+    }
+
+    /// Count relay traffic into `metrics`
+    pub fn with_telemetry(mut self, metrics: Arc<Metrics>) -> Self {
+        self.telemetry = metrics;
+        self
+    }
+
+    /// Send events to webhooks through `webhooks`
+    pub fn with_webhooks(mut self, webhooks: WebhookManager) -> Self {
+        self.webhooks = Arc::new(webhooks);
+        self
+    }
+
+    /// Keep uploaded recordings in `recordings`
+    pub fn with_recordings(mut self, recordings: RecordingManager) -> Self {
+        self.recordings = Arc::new(recordings);
+        self
+    }
+
+    /// Keep device notes, custom fields and inventory in `records`
+    pub fn with_device_records(mut self, records: DeviceRecords) -> Self {
+        self.device_records = Arc::new(records);
+        self
+    }
+
+    /// Keep session notes and note templates in `notes`
+    pub fn with_session_notes(mut self, notes: SessionNotes) -> Self {
+        self.session_notes = Arc::new(notes);
+        self
+    }
+
+    /// Keep crash reports in `reports`
+    pub fn with_crash_reports(mut self, reports: CrashReportStore) -> Self {
+        self.crash_reports = Arc::new(reports);
+        self
+    }
     
     /// Initialize all managers
     pub async fn initialize(&self) -> Result<(), String> {
@@ -134,7 +701,8 @@ impl DeviceManager {
             self.vpn_manager.initialize(),
             self.oidc_manager.initialize(),
             self.pam_manager.initialize(),
-            self.terminal_manager.initialize()
+            self.terminal_manager.initialize(),
+            self.release_manager.initialize()
         );
         
         // Check for any initialization errors
@@ -144,26 +712,85 @@ impl DeviceManager {
         init_results.3?;
         init_results.4?;
         init_results.5?;
+        init_results.6?;
+        self.webhooks.initialize().await?;
+        self.recordings.initialize().await?;
+        self.device_records.initialize().await?;
+        self.session_notes.initialize().await?;
+        self.crash_reports.initialize().await?;
+
+        if let Some(db) = &self.db {
+            self.restore_from_database(db).await
+                .map_err(|e| format!("Failed to restore state from database: {}", e))?;
+        }
         
         info!("All device managers initialized successfully");
         Ok(())
     }
 
+    /// Load agents and recent sessions persisted by a previous run.
+    ///
+    /// Nothing is connected yet, so every known agent starts out offline and
+    /// sessions left open by the previous run are closed as failed.
+    async fn restore_from_database(&self, db: &DatabaseService) -> anyhow::Result<()> {
+        db.mark_agents_offline().await?;
+        let interrupted = db.close_interrupted_sessions().await?;
+
+        let organizations = db.list_organizations().await?;
+        self.organizations.write().await.extend(organizations.into_iter().map(|organization| (organization.id, organization)));
+        let groups = db.list_device_groups().await?;
+        self.groups.write().await.extend(groups.into_iter().map(|group| (group.id, group)));
+        let policies = db.list_policies().await?;
+        self.policies.write().await.extend(policies.into_iter().map(|policy| (policy.id, policy)));
+
+        let agents = db.list_agents().await?;
+        let mut offline_agents = self.offline_agents.write().await;
+        let mut index = self.device_index.write().await;
+        for mut agent in agents {
+            agent.status = "offline".to_string();
+            index.insert(&agent);
+            offline_agents.insert(agent.id, agent);
+        }
+        let agent_count = offline_agents.len();
+        drop(index);
+        drop(offline_agents);
+
+        let sessions = db.recent_sessions(ENDED_SESSION_HISTORY as i64).await?;
+        let session_count = sessions.len();
+        self.ended_sessions.write().await.extend(sessions.into_iter().rev());
+
+        info!(
+            "Restored {} agents and {} sessions from database ({} interrupted sessions closed)",
+            agent_count, session_count, interrupted
+        );
+        Ok(())
+    }
+
     /// Register a new device connection
     pub async fn register_device(
         &self,
         registration: DeviceRegistration,
-        tx: mpsc::UnboundedSender<Message>,
+        tx: OutboundSender,
     ) -> Result<Uuid, String> {
         let agent_id = if let Some(id_str) = registration.agent_id {
             Uuid::parse_str(&id_str).map_err(|e| format!("Invalid agent ID: {}", e))?
         } else {
             Uuid::new_v4()
         };
+        let mut connection_info = HashMap::new();
+        if !registration.interfaces.is_empty() {
+            connection_info.insert("interfaces".to_string(), serde_json::json!(registration.interfaces));
+        }
+
+        // Groups, tags and aliases are assigned on the server, not reported by the agent
+        let (known_organization, group_id, tags, alias) = match self.get_agent(agent_id).await {
+            Some((known, _)) => (known.organization_id, known.group_id, known.tags, known.alias),
+            None => (None, None, Vec::new(), None),
+        };
 
         let agent = Agent {
             id: agent_id,
-            organization_id: None, // TODO: Get from authentication context
+            organization_id: registration.organization_id.or(known_organization),
             name: registration.name.unwrap_or_else(|| registration.hostname.clone()),
             hostname: Some(registration.hostname),
             platform: registration.platform,
@@ -173,11 +800,14 @@ impl DeviceManager {
             public_key: registration.public_key,
             last_seen: Some(Utc::now()),
             status: "online".to_string(),
-            connection_info: sqlx::types::Json(std::collections::HashMap::new()),
-            capabilities: sqlx::types::Json(std::collections::HashMap::new()),
+            connection_info: sqlx::types::Json(connection_info),
+            capabilities: sqlx::types::Json(registration.capabilities),
             settings: sqlx::types::Json(std::collections::HashMap::new()),
             created_at: Utc::now(),
             updated_at: Utc::now(),
+            group_id,
+            tags,
+            alias,
         };
 
         let connection = DeviceConnection {
@@ -186,22 +816,47 @@ impl DeviceManager {
             last_ping: Utc::now(),
             connection_time: Utc::now(),
             active_sessions: Vec::new(),
+            binary_protocol: None,
+            compression: false,
+            access_paused_until: None,
         };
 
         let mut devices = self.devices.write().await;
-        devices.insert(agent_id, connection);
+        if let Some(replaced) = devices.insert(agent_id, connection) {
+            // The same device connected again; its old socket is a zombie
+            info!("Device {} connected again, closing its previous connection", agent_id);
+            let _ = replaced.tx.send(Message::Close(Some(CloseFrame {
+                code: rendezvous::CLOSE_REPLACED,
+                reason: "replaced by a newer connection".into(),
+            })));
+        }
         drop(devices);
+        self.offline_agents.write().await.remove(&agent_id);
+        self.device_index.write().await.insert(&agent);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.upsert_agent(&agent).await {
+                warn!("Failed to persist device {}: {}", agent_id, e);
+            }
+        }
 
         info!("Device registered: {} ({})", agent.name, agent_id);
         
         // Broadcast device connection
         let _ = self.broadcast_tx.send(BroadcastMessage::DeviceConnected(agent_id));
+        if let Some(transition) = self.liveness.observe(agent_id, DeviceStatus::Online, Utc::now(), self.liveness_policy.debounce()).await {
+            self.announce_status(transition).await;
+        }
 
         Ok(agent_id)
     }
 
     /// Remove a device connection
     pub async fn disconnect_device(&self, agent_id: Uuid) {
+        self.disconnect(agent_id, Utc::now()).await;
+    }
+
+    async fn disconnect(&self, agent_id: Uuid, now: DateTime<Utc>) {
         let mut devices = self.devices.write().await;
         if let Some(connection) = devices.remove(&agent_id) {
             info!("Device disconnected: {} ({})", connection.agent.name, agent_id);
@@ -214,201 +869,2705 @@ impl DeviceManager {
                 .map(|(session_id, _)| *session_id)
                 .collect();
 
+            let mut ended = Vec::with_capacity(session_ids_to_remove.len());
             for session_id in session_ids_to_remove {
-                sessions.remove(&session_id);
+                if let Some(session_conn) = sessions.remove(&session_id) {
+                    let _ = session_conn.tx.send(Message::Close(None));
+                    let mut session = session_conn.session;
+                    mark_ended(&mut session);
+                    ended.push(session);
+                }
                 let _ = self.broadcast_tx.send(BroadcastMessage::SessionEnded(session_id));
             }
+            drop(sessions);
+            drop(devices);
+
+            for session in ended {
+                self.idle_tracker.remove(session.id).await;
+                self.session_tokens.revoke(session.id).await;
+                self.record_ended_session(session).await;
+            }
+            self.control.remove(agent_id).await;
+            if let Some(lane) = &self.udp_lane {
+                lane.close(agent_id).await;
+            }
+
+            let mut agent = connection.agent;
+            agent.status = "offline".to_string();
+            agent.last_seen = Some(connection.last_ping);
+            self.offline_agents.write().await.insert(agent_id, agent);
+
+            if let Some(db) = &self.db {
+                if let Err(e) = db.update_agent_status(agent_id, "offline", connection.last_ping).await {
+                    warn!("Failed to persist disconnect of device {}: {}", agent_id, e);
+                }
+            }
 
             let _ = self.broadcast_tx.send(BroadcastMessage::DeviceDisconnected(agent_id));
+            if let Some(transition) = self.liveness.observe(agent_id, DeviceStatus::Offline, now, self.liveness_policy.debounce()).await {
+                self.announce_status(transition).await;
+            }
         }
     }
 
-    /// Update device heartbeat
-    pub async fn update_device_heartbeat(&self, agent_id: Uuid) -> Result<(), String> {
+    /// Take a device offline at the agent's own request, typically because it
+    /// is shutting down, instead of waiting for its heartbeats to lapse
+    pub async fn deregister_device(&self, agent_id: Uuid, reason: &str) -> Result<(), String> {
         let mut devices = self.devices.write().await;
-        if let Some(connection) = devices.get_mut(&agent_id) {
-            connection.last_ping = Utc::now();
-            debug!("Heartbeat updated for device: {}", agent_id);
-            Ok(())
-        } else {
-            Err(format!("Device not found: {}", agent_id))
-        }
+        let connection = devices.get_mut(&agent_id)
+            .ok_or_else(|| format!("Device not found: {}", agent_id))?;
+        // It was alive until just now
+        connection.last_ping = Utc::now();
+        drop(devices);
+
+        info!("Device {} deregistered: {}", agent_id, reason);
+        self.disconnect_device(agent_id).await;
+        // Shutting down is no blip to wait out
+        self.announce_now(agent_id, DeviceStatus::Offline).await;
+        self.record_audit(
+            AuditLog::new("agent", "agent_deregistered")
+                .actor(agent_id.to_string())
+                .details(serde_json::json!({ "reason": reason })),
+        ).await;
+        Ok(())
     }
 
-    /// Create a new session
-    pub async fn create_session(
-        &self,
-        request: SessionRequest,
-        tx: mpsc::UnboundedSender<Message>,
-    ) -> Result<Uuid, String> {
-        // Verify device exists and is connected
-        let devices = self.devices.read().await;
-        if !devices.contains_key(&request.agent_id) {
-            return Err(format!("Device not found or offline: {}", request.agent_id));
+    /// Remove a temporary agent that joined with support code `code` for
+    /// good. Its row is kept, marked purged, so its sessions stay in history.
+    pub async fn purge_device(&self, agent_id: Uuid, code: &str, reason: &str) {
+        let closed = serde_json::json!({
+            "type": "SupportCodeClosed",
+            "code": code,
+            "reason": reason,
+        });
+        let _ = self.send_to_device(agent_id, Message::Text(closed.to_string())).await;
+        self.disconnect_device(agent_id).await;
+        self.announce_now(agent_id, DeviceStatus::Offline).await;
+        self.liveness.forget(agent_id).await;
+        self.offline_agents.write().await.remove(&agent_id);
+        self.device_index.write().await.remove(agent_id);
+        self.metrics.write().await.remove(&agent_id);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.update_agent_status(agent_id, "purged", Utc::now()).await {
+                warn!("Failed to persist purge of device {}: {}", agent_id, e);
+            }
         }
-        drop(devices);
+        info!("Temporary device {} purged: {}", agent_id, reason);
+    }
 
-        let session_id = Uuid::new_v4();
-        let session = Session {
-            id: session_id,
-            agent_id: request.agent_id,
-            user_id: request.user_id,
-            organization_id: None,
-            session_type: request.session_type.to_string(),
-            status: "connecting".to_string(),
-            started_at: Some(Utc::now()),
-            ended_at: None,
-            duration_seconds: None,
-            bytes_transferred: 0,
-            frames_captured: 0,
-            settings: sqlx::types::Json(std::collections::HashMap::new()),
-            metadata: sqlx::types::Json(std::collections::HashMap::new()),
-            created_at: Utc::now(),
-            updated_at: Utc::now(),
-        };
+    /// Done with support code `code` of `agent_id`: a temporary agent is
+    /// purged, an enrolled one that joined from its tray is only told
+    pub async fn close_support_code(&self, agent_id: Uuid, code: &SupportCode, reason: &str) {
+        if !code.enrolled {
+            return self.purge_device(agent_id, &code.code, reason).await;
+        }
+        let closed = serde_json::json!({
+            "type": "SupportCodeClosed",
+            "code": code.code,
+            "reason": reason,
+        });
+        let _ = self.send_to_device(agent_id, Message::Text(closed.to_string())).await;
+        info!("Support code {} of enrolled device {} closed: {}", code.code, agent_id, reason);
+    }
 
-        let session_connection = SessionConnection {
-            session: session.clone(),
-            tx,
-            connection_time: Utc::now(),
+    /// The end user of enrolled agent `agent_id` typed support code `code`
+    /// into its tray; the agent waits for the technician like a temporary
+    /// one, and stays enrolled once the session is over
+    pub async fn join_support_code(&self, agent_id: Uuid, code: &str) -> Result<SupportCode, SupportCodeError> {
+        let Some((agent, true)) = self.get_agent(agent_id).await else {
+            return Err(SupportCodeError::AgentOffline);
         };
+        let joined = self.support_codes.join_enrolled(code, agent_id, agent.organization_id, Utc::now()).await?;
+        self.record_audit(
+            AuditLog::new("agent", "support_code_joined")
+                .actor(agent_id.to_string())
+                .details(serde_json::json!({ "code": joined.code, "created_by": joined.created_by })),
+        ).await;
+        Ok(joined)
+    }
 
-        let mut sessions = self.sessions.write().await;
-        sessions.insert(session_id, session_connection);
-        drop(sessions);
-
-        // Add session to device's active sessions
-        let mut devices = self.devices.write().await;
-        if let Some(device) = devices.get_mut(&request.agent_id) {
-            device.active_sessions.push(session_id);
+    /// Drop support codes that expired unused and purge the agents waiting
+    /// on them, returning how many codes expired
+    pub async fn expire_support_codes(&self, now: DateTime<Utc>) -> usize {
+        let expired = self.support_codes.take_expired(now).await;
+        for code in &expired {
+            if let Some(agent_id) = code.agent_id {
+                self.close_support_code(agent_id, code, "expired").await;
+            }
         }
+        expired.len()
+    }
 
-        info!("Session created: {} for device {}", session_id, request.agent_id);
-        
-        // Broadcast session start
-        let _ = self.broadcast_tx.send(BroadcastMessage::SessionStarted(session_id, request.agent_id));
-
-        Ok(session_id)
+    /// Delete recordings past their organization's retention, or the
+    /// server's; how many went
+    pub async fn expire_recordings(&self, now: DateTime<Utc>) -> usize {
+        let overrides: HashMap<Uuid, u32> = self.organizations.read().await
+            .values()
+            .filter_map(|organization| {
+                recordings::retention_override(&organization.settings).map(|days| (organization.id, days))
+            })
+            .collect();
+        let expired = self.recordings.expire(now, &overrides).await;
+        for recording in &expired {
+            self.record_audit(
+                AuditLog::new("recording", "recording_expired")
+                    .agent(recording.agent_id)
+                    .session(recording.session_id)
+                    .details(serde_json::json!({
+                        "recording": recording.id,
+                        "started_at": recording.started_at,
+                        "size": recording.size,
+                    })),
+            ).await;
+        }
+        expired.len()
     }
 
-    /// End a session
-    pub async fn end_session(&self, session_id: Uuid) -> Result<(), String> {
-        let mut sessions = self.sessions.write().await;
-        if let Some(session_conn) = sessions.remove(&session_id) {
-            info!("Session ended: {}", session_id);
+    /// Start the session a support code was created for on the agent that
+    /// joined with it, using the code up
+    pub async fn start_support_session(
+        &self,
+        code: &str,
+        user_id: Uuid,
+        requester: &str,
+        is_admin: bool,
+        capabilities: Option<ViewerCapabilities>,
+    ) -> Result<Uuid, SupportCodeError> {
+        let support_code = self.support_codes.redeem(code, user_id, is_admin, Utc::now()).await?;
+        let agent_id = support_code.agent_id.ok_or(SupportCodeError::NotJoined)?;
 
-            // Remove session from device's active sessions
-            let mut devices = self.devices.write().await;
-            if let Some(device) = devices.get_mut(&session_conn.session.agent_id) {
-                device.active_sessions.retain(|&id| id != session_id);
-            }
+        let request = SessionRequest {
+            agent_id,
+            session_type: support_code.session_type.clone(),
+            user_id,
+            capabilities: capabilities.clone(),
+            bandwidth_kbps: None,
+            compress_frames: false,
+            ticket_ref: None,
+        };
+        let (tx, _) = outbound::channel();
+        let session_id = self.create_session(request, tx).await.map_err(|e| match e {
+            SessionCreateError::Unsupported(unsupported) => SupportCodeError::Unsupported(unsupported),
+            _ => SupportCodeError::AgentOffline,
+        })?;
+        if let Err(e) = self.support_codes.attach_session(&support_code.code, session_id).await {
+            // Another technician's request got there first
+            let _ = self.end_session(session_id).await;
+            return Err(e);
+        }
 
-            let _ = self.broadcast_tx.send(BroadcastMessage::SessionEnded(session_id));
-            Ok(())
-        } else {
-            Err(format!("Session not found: {}", session_id))
+        // The agent is waiting for exactly this, so it is asked directly
+        // instead of waiting for a viewer to connect
+        let mut request = serde_json::json!({
+            "type": "SessionRequest",
+            "session_id": session_id.to_string(),
+            "session_type": support_code.session_type.to_string(),
+            "requester": requester,
+        });
+        // The agent picks a codec the viewer decodes before its first frame
+        if let Some(capabilities) = capabilities {
+            request["capabilities"] = serde_json::json!(capabilities);
         }
+        if let Some(kbps) = self.bandwidth_cap(session_id).await {
+            request["bandwidth_kbps"] = kbps.into();
+        }
+        // Attended sessions wait for the person at the device when policy says so
+        let session_type = support_code.session_type.to_string();
+        request["requires_consent"] = self.requires_consent(agent_id, session_id, &session_type).await.into();
+        // The end user sees the branding banner before anything is captured
+        let organization_id = self.get_session(session_id).await.and_then(|session| session.organization_id);
+        if let Some(banner) = self.branding_manager.banner_for_new_session(session_id, organization_id).await {
+            request["banner"] = self.branding_manager.agent_banner(&banner, organization_id).await;
+        }
+        let _ = self.send_to_device(agent_id, Message::Text(request.to_string())).await;
+        self.route_session(session_id, None, None).await;
+        self.record_audit(
+            AuditLog::new("session", "support_code_redeemed")
+                .actor(user_id.to_string())
+                .session(session_id)
+                .details(serde_json::json!({ "code": support_code.code, "agent_id": agent_id })),
+        ).await;
+        Ok(session_id)
     }
 
-    /// Get all connected devices
-    pub async fn get_connected_devices(&self) -> Vec<Agent> {
-        let devices = self.devices.read().await;
-        devices.values().map(|conn| conn.agent.clone()).collect()
+    /// Seconds a backstage session on `agent_id` waits for approval, or
+    /// `None` when its policy lets it start straight away
+    pub async fn backstage_approval_ttl(&self, agent_id: Uuid) -> Option<chrono::Duration> {
+        let (policy, _) = self.effective_policy(agent_id).await?;
+        policy.backstage_approval_ttl().map(|secs| chrono::Duration::seconds(secs as i64))
     }
 
-    /// Get active sessions for a device
-    pub async fn get_device_sessions(&self, agent_id: Uuid) -> Vec<Session> {
-        let sessions = self.sessions.read().await;
-        sessions
-            .values()
-            .filter(|conn| conn.session.agent_id == agent_id)
-            .map(|conn| conn.session.clone())
-            .collect()
+    /// Hold a backstage session back until someone approves it. The device
+    /// must be able to run it now, so nobody approves a request bound to fail.
+    pub async fn request_backstage(
+        &self,
+        held: HeldSession,
+        requested_by: Uuid,
+        requester_name: &str,
+        justification: &str,
+        ttl: chrono::Duration,
+    ) -> Result<Approval, SessionCreateError> {
+        let (organization_id, _) = self.admit_session(&held.request).await?;
+        let approval = self.approvals
+            .request(held, organization_id, requested_by, requester_name, justification, ttl, Utc::now())
+            .await;
+        info!("Backstage session on {} by {} waits for approval {}", approval.agent_id, requester_name, approval.id);
+        self.webhooks.notify(WebhookEvent::BackstageApprovalRequested, organization_id, serde_json::json!({ "approval": approval })).await;
+        self.publish_approval(&approval).await;
+        Ok(approval)
     }
 
-    /// Send message to a specific device
-    pub async fn send_to_device(&self, agent_id: Uuid, message: Message) -> Result<(), String> {
-        let devices = self.devices.read().await;
-        if let Some(connection) = devices.get(&agent_id) {
-            connection.tx.send(message)
-                .map_err(|_| "Failed to send message to device".to_string())?;
-            Ok(())
-        } else {
-            Err(format!("Device not connected: {}", agent_id))
+    /// Approve a held backstage session as `approver` and start it as it
+    /// was asked for. A session that cannot start any more leaves the
+    /// request `Failed` with the reason.
+    pub async fn approve_backstage(&self, approval_id: Uuid, approver: Uuid) -> Result<Approval, ApprovalError> {
+        let approval = self.approvals.approve(approval_id, approver, Utc::now()).await?;
+        let held = approval.held.clone();
+        let (tx, _) = outbound::channel();
+        let session_id = match self.create_session(held.request.clone(), tx).await {
+            Ok(session_id) => session_id,
+            Err(e) => {
+                warn!("Approved backstage session {} could not start: {}", approval_id, e);
+                let approval = self.approvals.failed(approval_id, e.to_string(), Utc::now()).await?;
+                self.approval_decided(&approval).await;
+                return Ok(approval);
+            }
+        };
+        if let Some(grant_id) = held.access_grant_id {
+            self.access_grants.attach_session(grant_id, session_id).await;
         }
+        self.route_session(session_id, held.region, held.connection_type).await;
+        let launch = match self.session_tokens.mint(session_id, held.request.user_id, held.scopes, held.token_ttl, Utc::now()).await {
+            Ok((token, claims)) => Some(Launch {
+                launch_url: session_tokens::launch_url(session_id, &token),
+                token,
+                token_expires_at: claims.expires_at(),
+                scopes: claims.scopes,
+            }),
+            Err(e) => {
+                warn!("Failed to mint a token for approved backstage session {}: {}", session_id, e);
+                None
+            }
+        };
+        let approval = self.approvals.started(approval_id, session_id, launch).await?;
+        info!("Backstage session {} on {} approved by {}", session_id, approval.agent_id, approver);
+        self.approval_decided(&approval).await;
+        Ok(approval)
     }
 
-    /// Send message to a specific session
-    pub async fn send_to_session(&self, session_id: Uuid, message: Message) -> Result<(), String> {
-        let sessions = self.sessions.read().await;
-        if let Some(connection) = sessions.get(&session_id) {
-            connection.tx.send(message)
-                .map_err(|_| "Failed to send message to session".to_string())?;
-            Ok(())
-        } else {
-            Err(format!("Session not found: {}", session_id))
-        }
+    /// Deny a held backstage session, or withdraw it as its requester
+    pub async fn deny_backstage(&self, approval_id: Uuid, user_id: Uuid, reason: Option<String>) -> Result<Approval, ApprovalError> {
+        let approval = self.approvals.deny(approval_id, user_id, reason, Utc::now()).await?;
+        info!("Backstage request {} on {} denied by {}", approval.id, approval.agent_id, user_id);
+        self.approval_decided(&approval).await;
+        Ok(approval)
+    }
+
+    /// Expire backstage requests nobody decided in time; returns how many
+    pub async fn expire_approvals(&self, now: DateTime<Utc>) -> usize {
+        let expired = self.approvals.expire(now).await;
+        for approval in &expired {
+            info!("Backstage request {} on {} expired", approval.id, approval.agent_id);
+            self.record_audit(approvals::audit(approval, "backstage_request_expired")).await;
+            self.approval_decided(approval).await;
+        }
+        expired.len()
+    }
+
+    /// Tell the requester and the approvers what became of a request
+    async fn approval_decided(&self, approval: &Approval) {
+        self.webhooks.notify(WebhookEvent::BackstageApprovalDecided, approval.organization_id, serde_json::json!({ "approval": approval })).await;
+        self.publish_approval(approval).await;
+    }
+
+    async fn publish_approval(&self, approval: &Approval) {
+        let scope = self.device_scope(approval.agent_id).await;
+        self.live_events.publish(scope, LiveEvent::BackstageApproval { approval: approval.clone() });
+    }
+
+    /// Attach the relay WebSocket sender to a session
+    pub async fn attach_session_channel(&self, session_id: Uuid, tx: OutboundSender) {
+        let mut sessions = self.sessions.write().await;
+        let Some(connection) = sessions.get_mut(&session_id) else {
+            return;
+        };
+        connection.tx = tx;
+        debug!("Relay channel attached for session: {}", session_id);
+
+        // Viewers learn who holds control as soon as they connect
+        let agent_id = connection.session.agent_id;
+        let holder = self.control.holder(agent_id).await;
+        let message = control_changed(agent_id, None, holder);
+        let _ = connection.tx.send(Message::Text(message.to_string()));
+
+        let streaming = sessions.values()
+            .any(|other| other.session.agent_id == agent_id && other.session.frames_captured > 0);
+        drop(sessions);
+
+        // Joining a running stream, the viewer has nothing to decode against
+        // until the next keyframe
+        if streaming {
+            if let Err(e) = self.request_keyframe(session_id).await {
+                debug!("Failed to request a keyframe for session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// Attach the WebSocket of a viewer `user_id` opened. The colleague a
+    /// session is being transferred to watches alongside its technician, or
+    /// takes over at once; anyone else attaches as the technician.
+    pub async fn attach_viewer(&self, session_id: Uuid, user_id: Uuid, tx: OutboundSender) {
+        let Some(transfer) = self.transfers.attach(session_id, user_id, Utc::now()).await else {
+            self.attach_session_channel(session_id, tx).await;
+            return;
+        };
+        let agent_id = {
+            let mut sessions = self.sessions.write().await;
+            let Some(connection) = sessions.get_mut(&session_id) else {
+                return;
+            };
+            connection.observers.insert(user_id, tx.clone());
+            connection.session.agent_id
+        };
+        info!("Viewer of {} attached to session {} for transfer {}", user_id, session_id, transfer.id);
+
+        let _ = tx.send(Message::Text(viewer_role(false).to_string()));
+        let holder = self.control.holder(agent_id).await;
+        let _ = tx.send(Message::Text(control_changed(agent_id, None, holder).to_string()));
+        if let Err(e) = self.request_keyframe(session_id).await {
+            debug!("Failed to request a keyframe for session {}: {}", session_id, e);
+        }
+        if transfer.state == TransferState::Completed {
+            self.hand_over(&transfer).await;
+        } else {
+            self.notify_transfer(&transfer).await;
+        }
+    }
+
+    /// The viewer `user_id` opened left a session. An observer just goes;
+    /// the technician leaving ends the session, unless a colleague is
+    /// taking it over or already did.
+    pub async fn detach_viewer(&self, session_id: Uuid, user_id: Uuid) {
+        let (observer, operator) = {
+            let mut sessions = self.sessions.write().await;
+            let Some(connection) = sessions.get_mut(&session_id) else {
+                return;
+            };
+            (connection.observers.remove(&user_id).is_some(), connection.session.user_id == user_id)
+        };
+        let now = Utc::now();
+        if observer {
+            if let Some(transfer) = self.transfers.abandon(session_id, user_id, now).await {
+                info!("Transfer {} of session {} abandoned: the new technician left", transfer.id, session_id);
+                self.transfer_ended(&transfer).await;
+            }
+            return;
+        }
+        if operator {
+            match self.transfers.original_left(session_id, now).await {
+                Some(transfer) if transfer.state == TransferState::Completed => {
+                    self.hand_over(&transfer).await;
+                    return;
+                }
+                Some(transfer) => {
+                    info!("Technician left session {}; it waits for transfer {}", session_id, transfer.id);
+                    self.notify_transfer(&transfer).await;
+                    return;
+                }
+                None => {}
+            }
+        } else if self.transfers.handed_off(session_id, user_id).await {
+            return;
+        }
+        let _ = self.end_session(session_id).await;
+    }
+
+    /// Whether `user_id` only watches `session_id` alongside its technician
+    pub async fn is_observer(&self, session_id: Uuid, user_id: Uuid) -> bool {
+        self.sessions.read().await.get(&session_id)
+            .is_some_and(|connection| connection.observers.contains_key(&user_id))
+    }
+
+    /// Send a message to the viewer `user_id` has on a session only, whether
+    /// it runs the session or watches it
+    pub async fn send_to_viewer(&self, session_id: Uuid, user_id: Uuid, message: Message) -> Result<(), String> {
+        let tx = {
+            let sessions = self.sessions.read().await;
+            let connection = sessions.get(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            connection.observers.get(&user_id).unwrap_or(&connection.tx).clone()
+        };
+        let priority = outbound::classify(&message);
+        tx.send_wait(message, priority).await
+            .map_err(|e| format!("Failed to send message to viewer: {}", e))
+    }
+
+    /// Offer a session to another technician
+    pub async fn offer_transfer(
+        &self,
+        session: &Session,
+        to_user: Uuid,
+        shadow: bool,
+        keep_original: bool,
+        ttl: chrono::Duration,
+    ) -> Result<Transfer, TransferError> {
+        let transfer = self.transfers.offer(session, to_user, shadow, keep_original, ttl, Utc::now()).await?;
+        info!("Session {} offered to {} (transfer {})", session.id, to_user, transfer.id);
+        self.notify_transfer(&transfer).await;
+        Ok(transfer)
+    }
+
+    /// Withdraw or decline a transfer offer
+    pub async fn cancel_transfer(&self, transfer_id: Uuid, user_id: Uuid) -> Result<Transfer, TransferError> {
+        let transfer = self.transfers.cancel(transfer_id, user_id, Utc::now()).await?;
+        self.transfer_ended(&transfer).await;
+        Ok(transfer)
+    }
+
+    /// Pass control of a session to the colleague shadowing it
+    pub async fn complete_transfer(&self, session_id: Uuid) -> Result<Transfer, String> {
+        let transfer = self.transfers.complete(session_id, Utc::now()).await
+            .ok_or_else(|| format!("Nobody is shadowing session {} to take it over", session_id))?;
+        self.hand_over(&transfer).await;
+        Ok(transfer)
+    }
+
+    /// Expire transfer offers nobody took up in time; returns how many
+    pub async fn expire_transfers(&self, now: DateTime<Utc>) -> usize {
+        let expired = self.transfers.expire(now).await;
+        for transfer in &expired {
+            info!("Transfer {} of session {} expired", transfer.id, transfer.session_id);
+            self.record_audit(
+                AuditLog::new("session", "session_transfer_expired")
+                    .agent(transfer.agent_id)
+                    .session(transfer.session_id)
+                    .details(serde_json::json!({ "transfer_id": transfer.id, "to_user": transfer.to_user })),
+            ).await;
+            self.transfer_ended(transfer).await;
+        }
+        expired.len()
+    }
+
+    /// Tell everyone watching a session where its transfer stands
+    pub async fn notify_transfer(&self, transfer: &Transfer) {
+        let message = serde_json::json!({ "type": "SessionTransfer", "transfer": transfer });
+        if let Err(e) = self.send_to_session(transfer.session_id, Message::Text(message.to_string())).await {
+            debug!("Failed to tell session {} about its transfer: {}", transfer.session_id, e);
+        }
+    }
+
+    /// A transfer ended without control passing: the colleague's viewer, if
+    /// attached, goes, and a session whose technician already left ends
+    async fn transfer_ended(&self, transfer: &Transfer) {
+        self.notify_transfer(transfer).await;
+        let shadow = self.sessions.write().await.get_mut(&transfer.session_id)
+            .and_then(|connection| connection.observers.remove(&transfer.to_user));
+        if let Some(tx) = shadow {
+            let _ = tx.send(Message::Close(None));
+        }
+        if transfer.original_left {
+            info!("Session {} ends with transfer {}, its technician having left", transfer.session_id, transfer.id);
+            let _ = self.end_session(transfer.session_id).await;
+        }
+    }
+
+    /// Make the transfer's colleague the session's technician. Their viewer
+    /// takes the technician's place, and with it whatever control the
+    /// session holds; the agent keeps its session and only learns the new
+    /// technician's name.
+    async fn hand_over(&self, transfer: &Transfer) {
+        let keep_original = transfer.keep_original && !transfer.original_left;
+        let (session, operator, previous) = {
+            let mut sessions = self.sessions.write().await;
+            let Some(connection) = sessions.get_mut(&transfer.session_id) else {
+                return;
+            };
+            let Some(tx) = connection.observers.remove(&transfer.to_user) else {
+                return;
+            };
+            let previous = std::mem::replace(&mut connection.tx, tx.clone());
+            if keep_original {
+                connection.observers.insert(transfer.from_user, previous.clone());
+            }
+            connection.session.user_id = transfer.to_user;
+            connection.session.updated_at = Utc::now();
+            (connection.session.clone(), tx, previous)
+        };
+        info!("Session {} handed from {} to {}", session.id, transfer.from_user, transfer.to_user);
+
+        let _ = operator.send(Message::Text(viewer_role(true).to_string()));
+        let holder = self.control.holder(session.agent_id).await;
+        let _ = operator.send(Message::Text(control_changed(session.agent_id, None, holder).to_string()));
+        let _ = previous.send(match keep_original {
+            true => Message::Text(viewer_role(false).to_string()),
+            false => Message::Close(None),
+        });
+
+        let name = transfer.to_name.clone().unwrap_or_else(|| transfer.to_user.to_string());
+        let message = serde_json::json!({
+            "type": "SessionOperatorChanged",
+            "session_id": session.id.to_string(),
+            "requester": name,
+        });
+        if let Err(e) = self.send_to_device(session.agent_id, Message::Text(message.to_string())).await {
+            debug!("Failed to tell device {} about its new operator: {}", session.agent_id, e);
+        }
+        if let Some(db) = &self.db {
+            if let Err(e) = db.update_session_user(session.id, transfer.to_user).await {
+                warn!("Failed to persist the new technician of session {}: {}", session.id, e);
+            }
+        }
+
+        self.notify_transfer(transfer).await;
+        let event = SessionEvent::new(session.id, SessionEventKind::Other, name, serde_json::json!({
+            "event_type": "session_transferred",
+            "from_user": transfer.from_user,
+            "to_user": transfer.to_user,
+        }));
+        self.record_session_events(&session, transfer.to_user, vec![event]).await;
+        self.record_audit(
+            AuditLog::new("session", "session_transfer_completed")
+                .actor(transfer.to_user.to_string())
+                .agent(session.agent_id)
+                .session(session.id)
+                .details(serde_json::json!({
+                    "transfer_id": transfer.id,
+                    "from_user": transfer.from_user,
+                    "original_left": transfer.original_left,
+                })),
+        ).await;
+    }
+
+    /// Ask the agent behind a session to encode its next frame as a keyframe
+    pub async fn request_keyframe(&self, session_id: Uuid) -> Result<(), String> {
+        let message = serde_json::json!({ "type": "RequestKeyframe", "session_id": session_id.to_string() });
+        self.send_to_session_agent(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Keep the codec the agent confirmed for a session and pass it on to
+    /// the viewer, which needs it before the first frame
+    pub async fn record_stream_config(&self, session_id: Uuid, config: serde_json::Value) -> Result<(), String> {
+        {
+            let mut sessions = self.sessions.write().await;
+            let connection = sessions.get_mut(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            connection.session.settings.insert(codecs::STREAM_CONFIG_SETTING.to_string(), config.clone());
+        }
+        let message = serde_json::json!({ "type": "StreamConfig", "session_id": session_id.to_string(), "config": config });
+        self.send_to_session(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Ask the agent for a simpler codec after the viewer failed to decode
+    /// the stream; the session goes on
+    pub async fn downgrade_stream(&self, session_id: Uuid, reason: Option<&str>) -> Result<(), String> {
+        let message = serde_json::json!({
+            "type": "StreamDowngrade",
+            "session_id": session_id.to_string(),
+            "reason": reason,
+        });
+        self.send_to_session_agent(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Turn the agent's cursor updates for a session on or off, remembering
+    /// the choice in the session's settings
+    pub async fn set_cursor_streaming(&self, session_id: Uuid, enabled: bool) -> Result<(), String> {
+        {
+            let mut sessions = self.sessions.write().await;
+            let connection = sessions.get_mut(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            connection.session.settings.insert(CURSOR_STREAMING_SETTING.to_string(), serde_json::Value::Bool(enabled));
+        }
+        let message = serde_json::json!({
+            "type": "SetCursorStreaming",
+            "session_id": session_id.to_string(),
+            "enabled": enabled,
+        });
+        self.send_to_session_agent(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Ask the agent behind a control session to switch its input mode; the
+    /// switch takes effect once the agent confirms it
+    pub async fn set_input_mode(&self, session_id: Uuid, mode: InputMode) -> Result<(), String> {
+        {
+            let sessions = self.sessions.read().await;
+            let connection = sessions.get(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            if connection.session.session_type != "control" {
+                return Err("Session does not have control permissions".to_string());
+            }
+        }
+        let message = serde_json::json!({
+            "type": "SetInputMode",
+            "session_id": session_id.to_string(),
+            "mode": mode,
+        });
+        self.send_to_session_agent(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Record the input mode the agent applies for a session and tell the
+    /// viewer, which waits for this before streaming relative movement
+    pub async fn confirm_input_mode(&self, session_id: Uuid, mode: InputMode, reason: Option<&str>) -> Result<(), String> {
+        {
+            let mut sessions = self.sessions.write().await;
+            let connection = sessions.get_mut(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            connection.session.settings.insert(INPUT_MODE_SETTING.to_string(), serde_json::json!(mode));
+        }
+        let message = serde_json::json!({
+            "type": "InputModeChanged",
+            "session_id": session_id.to_string(),
+            "mode": mode,
+            "reason": reason,
+        });
+        self.send_to_session(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Ask the agent behind a control session to validate its input without
+    /// injecting it, or to inject it again; takes effect once the agent
+    /// confirms
+    pub async fn set_input_preview(&self, session_id: Uuid, enabled: bool) -> Result<(), String> {
+        {
+            let sessions = self.sessions.read().await;
+            let connection = sessions.get(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            if connection.session.session_type != "control" {
+                return Err("Session does not have control permissions".to_string());
+            }
+        }
+        let message = serde_json::json!({
+            "type": "SetInputPreview",
+            "session_id": session_id.to_string(),
+            "enabled": enabled,
+        });
+        self.send_to_session_agent(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Record whether the agent previews a session's input and tell the viewer
+    pub async fn confirm_input_preview(&self, session_id: Uuid, enabled: bool) -> Result<(), String> {
+        {
+            let mut sessions = self.sessions.write().await;
+            let connection = sessions.get_mut(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            connection.session.settings.insert(INPUT_PREVIEW_SETTING.to_string(), serde_json::Value::Bool(enabled));
+        }
+        let message = serde_json::json!({
+            "type": "InputPreviewChanged",
+            "session_id": session_id.to_string(),
+            "enabled": enabled,
+        });
+        self.send_to_session(session_id, Message::Text(message.to_string())).await
+    }
+
+    /// Cap a session's bandwidth, or lift its cap with `None`, held to the
+    /// ceiling of the agent's policy. The agent enforces the cap and the
+    /// viewer is told what it came to; returns the cap in force.
+    pub async fn set_bandwidth_cap(&self, session_id: Uuid, kbps: Option<u32>) -> Result<Option<u32>, String> {
+        let agent_id = self.sessions.read().await.get(&session_id)
+            .map(|connection| connection.session.agent_id)
+            .ok_or_else(|| format!("Session not found: {}", session_id))?;
+        let ceiling = self.effective_policy(agent_id).await.and_then(|(policy, _)| policy.max_bandwidth_kbps);
+        let cap = bandwidth::effective_cap(kbps, ceiling);
+        {
+            let mut sessions = self.sessions.write().await;
+            let connection = sessions.get_mut(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            match cap {
+                Some(kbps) => connection.session.settings.insert(BANDWIDTH_CAP_SETTING.to_string(), serde_json::json!(kbps)),
+                None => connection.session.settings.remove(BANDWIDTH_CAP_SETTING),
+            };
+        }
+        let message = serde_json::json!({
+            "type": "SetBandwidthCap",
+            "session_id": session_id.to_string(),
+            "kbps": cap,
+        });
+        self.send_to_session_agent(session_id, Message::Text(message.to_string())).await?;
+        let message = serde_json::json!({
+            "type": "BandwidthCapChanged",
+            "session_id": session_id.to_string(),
+            "kbps": cap,
+            "ceiling_kbps": ceiling,
+        });
+        self.send_to_session(session_id, Message::Text(message.to_string())).await?;
+        Ok(cap)
+    }
+
+    /// Bandwidth cap in force for a session, if it has one
+    pub async fn bandwidth_cap(&self, session_id: Uuid) -> Option<u32> {
+        let sessions = self.sessions.read().await;
+        sessions.get(&session_id)?
+            .session.settings.get(BANDWIDTH_CAP_SETTING)
+            .and_then(|kbps| kbps.as_u64())
+            .map(|kbps| kbps as u32)
+    }
+
+    /// Pass a cursor update on to the viewer, unless it turned them off;
+    /// returns whether it was sent
+    pub async fn relay_cursor_update(&self, session_id: Uuid, update: serde_json::Value) -> Result<bool, String> {
+        let enabled = {
+            let sessions = self.sessions.read().await;
+            let connection = sessions.get(&session_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?;
+            // Updates already on the wire when the viewer opted out are dropped
+            connection.session.settings.get(CURSOR_STREAMING_SETTING).and_then(|v| v.as_bool()).unwrap_or(true)
+        };
+        if !enabled {
+            return Ok(false);
+        }
+        self.send_to_session(session_id, Message::Text(update.to_string())).await?;
+        Ok(true)
+    }
+
+    /// Update device heartbeat
+    pub async fn update_device_heartbeat(&self, agent_id: Uuid) -> Result<(), String> {
+        let now = Utc::now();
+        let mut devices = self.devices.write().await;
+        let connection = devices.get_mut(&agent_id)
+            .ok_or_else(|| format!("Device not found: {}", agent_id))?;
+        connection.last_ping = now;
+        // Degraded devices stay so until the sweep sees them recover
+        let status = connection.agent.status.clone();
+        drop(devices);
+        self.rendezvous.refresh(agent_id, now).await;
+        debug!("Heartbeat updated for device: {}", agent_id);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.update_agent_status(agent_id, &status, now).await {
+                warn!("Failed to persist heartbeat of device {}: {}", agent_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the metrics from an agent's heartbeat
+    pub async fn record_metrics(&self, agent_id: Uuid, metrics: DeviceMetrics) -> Result<(), String> {
+        self.update_device_heartbeat(agent_id).await?;
+
+        let sample = MetricsSample { recorded_at: Utc::now(), metrics };
+        if sample.metrics.crashed {
+            warn!("Device {} crashed since its last heartbeat", agent_id);
+            self.crash_reports.note_crash(agent_id, sample.recorded_at).await;
+        }
+        let mut all = self.metrics.write().await;
+        let history = all.entry(agent_id).or_default();
+        // Agents repeat their update report until it is resolved, so only a
+        // change is news
+        let failed_update = sample.metrics.update.clone()
+            .filter(|update| update.status == "failed")
+            .filter(|update| history.back().and_then(|previous| previous.metrics.update.as_ref()) != Some(update));
+        if history.len() >= METRICS_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(sample.clone());
+        drop(all);
+
+        let scope = self.device_scope(agent_id).await;
+        if let (Some(update), EventScope::Device { organization_id, .. }) = (failed_update, &scope) {
+            let data = serde_json::json!({ "device_id": agent_id, "version": update.version, "error": update.error });
+            self.webhooks.notify(WebhookEvent::AgentUpdateFailed, *organization_id, data).await;
+        }
+        self.live_events.publish(scope, LiveEvent::DeviceMetrics { device_id: agent_id, metrics: sample });
+        Ok(())
+    }
+
+    /// Latest heartbeat metrics of an agent
+    pub async fn latest_metrics(&self, agent_id: Uuid) -> Option<MetricsSample> {
+        self.metrics.read().await.get(&agent_id).and_then(|history| history.back().cloned())
+    }
+
+    /// Buffered heartbeat metrics of an agent recorded at or after `since`, oldest first
+    pub async fn metrics_history(&self, agent_id: Uuid, since: DateTime<Utc>) -> Vec<MetricsSample> {
+        self.metrics.read().await
+            .get(&agent_id)
+            .map(|history| history.iter().filter(|sample| sample.recorded_at >= since).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Add a command result to a session's timeline. Returns false when a
+    /// command with the same ID was already recorded.
+    pub async fn record_session_command(&self, session: &Session, user_id: Uuid, command: SessionCommand) -> bool {
+        // Sessions from before a restart continue the history in the database
+        let stored = if self.session_commands.read().await.contains_key(&session.id) {
+            Vec::new()
+        } else {
+            self.stored_session_events(session.id, COMMAND_EVENT).await
+        };
+
+        {
+            let mut all = self.session_commands.write().await;
+            let history = all.entry(session.id).or_insert_with(|| stored.into_iter().collect());
+            if history.iter().any(|recorded| recorded.id == command.id) {
+                return false;
+            }
+            while history.len() >= SESSION_COMMAND_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(command.clone());
+        }
+
+        self.persist_session_event(session, user_id, COMMAND_EVENT, command.execution_time, &command).await;
+        true
+    }
+
+    /// Commands run in a session, oldest first
+    pub async fn session_commands(&self, session_id: Uuid) -> Vec<SessionCommand> {
+        if let Some(history) = self.session_commands.read().await.get(&session_id) {
+            return history.iter().cloned().collect();
+        }
+        self.stored_session_events(session_id, COMMAND_EVENT).await
+    }
+
+    /// Add timeline events reported by a session window, push them to the
+    /// session's viewers and return those that were not recorded before
+    pub async fn record_session_events(&self, session: &Session, user_id: Uuid, events: Vec<SessionEvent>) -> Vec<SessionEvent> {
+        let stored = if self.session_events.read().await.contains_key(&session.id) {
+            Vec::new()
+        } else {
+            self.stored_session_events(session.id, TIMELINE_EVENT).await
+        };
+
+        let added = {
+            let mut all = self.session_events.write().await;
+            let timeline = all.entry(session.id).or_insert_with(|| {
+                let mut timeline = Vec::new();
+                session_events::merge_events(&mut timeline, stored);
+                timeline
+            });
+            let added = session_events::merge_events(timeline, events);
+            if timeline.len() > SESSION_EVENT_HISTORY {
+                let excess = timeline.len() - SESSION_EVENT_HISTORY;
+                timeline.drain(..excess);
+            }
+            added
+        };
+
+        for event in &added {
+            self.persist_session_event(session, user_id, TIMELINE_EVENT, event.timestamp, event).await;
+            // The web Timeline tab follows the session's WebSocket
+            let update = serde_json::json!({ "type": "SessionEvent", "event": event });
+            if let Err(e) = self.send_to_session(session.id, Message::Text(update.to_string())).await {
+                debug!("Failed to push timeline event to session {}: {}", session.id, e);
+            }
+        }
+        added
+    }
+
+    /// Timeline events of a session at or after `since`, in timeline order
+    pub async fn session_events(&self, session_id: Uuid, since: Option<DateTime<Utc>>) -> Vec<SessionEvent> {
+        let mut events = match self.session_events.read().await.get(&session_id) {
+            Some(timeline) => timeline.clone(),
+            None => {
+                let mut timeline = Vec::new();
+                session_events::merge_events(&mut timeline, self.stored_session_events(session_id, TIMELINE_EVENT).await);
+                timeline
+            }
+        };
+        if let Some(since) = since {
+            events.retain(|event| event.timestamp >= since);
+        }
+        events
+    }
+
+    /// Keep `data` in the session's audit log when a database is configured
+    async fn persist_session_event<T: Serialize>(
+        &self,
+        session: &Session,
+        user_id: Uuid,
+        event_type: &str,
+        timestamp: DateTime<Utc>,
+        data: &T,
+    ) {
+        let Some(db) = &self.db else {
+            return;
+        };
+        let event_data = match serde_json::to_value(data) {
+            Ok(serde_json::Value::Object(fields)) => fields.into_iter().collect(),
+            _ => HashMap::new(),
+        };
+        let event = SessionAuditLog {
+            id: Uuid::new_v4(),
+            session_id: session.id,
+            event_type: event_type.to_string(),
+            event_data: sqlx::types::Json(event_data),
+            timestamp,
+            user_id: Some(user_id),
+            agent_id: Some(session.agent_id),
+        };
+        if let Err(e) = db.log_session_event(&event).await {
+            warn!("Failed to persist {} of session {}: {}", event_type, session.id, e);
+        }
+    }
+
+    /// Session audit log entries of `event_type` decoded from their event data
+    async fn stored_session_events<T: serde::de::DeserializeOwned>(&self, session_id: Uuid, event_type: &str) -> Vec<T> {
+        let Some(db) = &self.db else {
+            return Vec::new();
+        };
+        match db.get_session_events(session_id, event_type).await {
+            Ok(events) => events
+                .into_iter()
+                .filter_map(|event| serde_json::to_value(event.event_data.0).ok())
+                .filter_map(|fields| serde_json::from_value(fields).ok())
+                .collect(),
+            Err(e) => {
+                warn!("Failed to load {} entries of session {}: {}", event_type, session_id, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Create a new session
+    /// Check the device is connected and its agent and policy allow
+    /// `request`, returning its organization and the policy's bandwidth
+    /// ceiling
+    async fn admit_session(&self, request: &SessionRequest) -> Result<(Option<Uuid>, Option<u32>), SessionCreateError> {
+        let devices = self.devices.read().await;
+        let Some(connection) = devices.get(&request.agent_id) else {
+            return Err(SessionCreateError::Offline(request.agent_id));
+        };
+        let organization_id = connection.agent.organization_id;
+        let supported = connection.agent.capabilities.check(AgentCapability::required_for(&request.session_type));
+        let paused_until = connection.access_paused_until.filter(|&until| until > Utc::now());
+        drop(devices);
+        if let Some(until) = paused_until {
+            return Err(SessionCreateError::Paused(request.agent_id, until));
+        }
+        supported.map_err(SessionCreateError::Unsupported)?;
+
+        let session_type = request.session_type.to_string();
+        let mut ceiling = None;
+        if let Some((policy, _)) = self.effective_policy(request.agent_id).await {
+            if !policy.allows_session(&session_type) {
+                return Err(SessionCreateError::Refused(format!(
+                    "Policy does not allow {} sessions on device {}",
+                    session_type, request.agent_id
+                )));
+            }
+            ceiling = policy.max_bandwidth_kbps;
+        }
+        Ok((organization_id, ceiling))
+    }
+
+    pub async fn create_session(
+        &self,
+        request: SessionRequest,
+        tx: OutboundSender,
+    ) -> Result<Uuid, SessionCreateError> {
+        let (organization_id, ceiling) = self.admit_session(&request).await?;
+        let session_type = request.session_type.to_string();
+
+        let session_id = Uuid::new_v4();
+        let mut settings = std::collections::HashMap::new();
+        if let Some(capabilities) = &request.capabilities {
+            settings.insert(codecs::CAPABILITIES_SETTING.to_string(), serde_json::json!(capabilities));
+        }
+        if let Some(kbps) = bandwidth::effective_cap(request.bandwidth_kbps, ceiling) {
+            settings.insert(BANDWIDTH_CAP_SETTING.to_string(), serde_json::json!(kbps));
+        }
+        if request.compress_frames {
+            settings.insert(COMPRESS_FRAMES_SETTING.to_string(), serde_json::json!(true));
+        }
+        let session = Session {
+            id: session_id,
+            agent_id: request.agent_id,
+            user_id: request.user_id,
+            organization_id,
+            session_type: session_type.clone(),
+            status: "connecting".to_string(),
+            started_at: Some(Utc::now()),
+            ended_at: None,
+            duration_seconds: None,
+            bytes_transferred: 0,
+            frames_captured: 0,
+            settings: sqlx::types::Json(settings),
+            metadata: sqlx::types::Json(std::collections::HashMap::new()),
+            ticket_ref: request.ticket_ref,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+
+        // Count and insert under one lock so simultaneous requests cannot both take the last slot
+        let mut sessions = self.sessions.write().await;
+        if let Some(limit) = self.session_policy.session_limit(&session_type) {
+            let open = sessions.values()
+                .filter(|conn| conn.session.agent_id == request.agent_id && conn.session.session_type == session_type)
+                .count();
+            if open >= limit as usize {
+                return Err(SessionCreateError::Refused(format!(
+                    "Device {} already has {} of {} allowed {} sessions",
+                    request.agent_id, open, limit, session_type
+                )));
+            }
+        }
+        sessions.insert(session_id, SessionConnection {
+            session: session.clone(),
+            tx,
+            input: Arc::new(tokio::sync::Mutex::new(InputCoalescer::new(self.input_window))),
+            observers: HashMap::new(),
+            connection_time: Utc::now(),
+        });
+        drop(sessions);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.create_session(&session).await {
+                warn!("Failed to persist session {}: {}", session_id, e);
+            }
+        }
+
+        // Add session to device's active sessions
+        let mut devices = self.devices.write().await;
+        if let Some(device) = devices.get_mut(&request.agent_id) {
+            device.active_sessions.push(session_id);
+        }
+        drop(devices);
+
+        info!("Session created: {} for device {}", session_id, request.agent_id);
+        self.idle_tracker.start(session_id, &session_type, Utc::now()).await;
+        
+        // The first control session on a device gets to drive it
+        if session_type == "control" {
+            let outcome = self.control.claim(request.agent_id, session_id).await;
+            self.apply_control_outcome(request.agent_id, outcome).await;
+        }
+        
+        // Broadcast session start
+        let _ = self.broadcast_tx.send(BroadcastMessage::SessionStarted(session_id, request.agent_id));
+        let scope = self.device_scope(request.agent_id).await;
+        self.webhooks.notify(WebhookEvent::SessionStarted, session.organization_id, serde_json::json!({ "session": session })).await;
+        self.live_events.publish(scope, LiveEvent::SessionStarted { session });
+
+        Ok(session_id)
+    }
+
+    /// Place a session on a relay node near `technician_region` or the
+    /// agent's region. Agents don't follow routes yet and keep relaying
+    /// through this server, so only the caller hears about the node.
+    pub async fn route_session(
+        &self,
+        session_id: Uuid,
+        technician_region: Option<String>,
+        connection_type: Option<ConnectionType>,
+    ) -> Option<SessionRoute> {
+        let session = self.sessions.read().await.get(&session_id).map(|conn| conn.session.clone())?;
+        let hints = RouteHints {
+            agent_region: self.get_agent(session.agent_id).await.and_then(|(agent, _)| agent.region_hint()),
+            technician_region,
+        };
+        let mut route = self.relay_manager
+            .create_route(session_id.to_string(), session.agent_id.to_string(), session.user_id.to_string(), &hints, Utc::now())
+            .await;
+
+        if route.relay_node.is_none() && matches!(connection_type, Some(ConnectionType::Hybrid | ConnectionType::RelayedUdp)) {
+            // Frames of sessions relayed elsewhere never reach this server's lane
+            match &self.udp_lane {
+                Some(lane) => {
+                    let offer = lane.offer(session.agent_id, session_id, std::time::Instant::now()).await;
+                    let message = serde_json::json!({
+                        "type": "UdpLaneOffer",
+                        "session_id": session_id.to_string(),
+                        "port": offer.port,
+                        "token": offer.token,
+                    });
+                    let _ = self.send_to_device(session.agent_id, Message::Text(message.to_string())).await;
+                    route.connection_type = ConnectionType::Hybrid;
+                    self.relay_manager.set_connection_type(&session_id.to_string(), ConnectionType::Hybrid).await;
+                }
+                None => debug!("Session {} asked for UDP, but no UDP lane is configured", session_id),
+            }
+        }
+        Some(route)
+    }
+
+    /// Move sessions whose agent lost its UDP lane back onto the WebSocket.
+    ///
+    /// Frames in flight on the lane are gone, so each session gets a
+    /// keyframe. `notify_agent` is false when the agent reported the failure.
+    pub async fn udp_lane_closed(&self, agent_id: Uuid, sessions: Vec<Uuid>, notify_agent: bool) {
+        for session_id in sessions {
+            self.relay_manager.set_connection_type(&session_id.to_string(), ConnectionType::RelayedTcp).await;
+            if notify_agent {
+                let message = serde_json::json!({ "type": "UdpLaneClosed", "session_id": session_id.to_string() });
+                let _ = self.send_to_device(agent_id, Message::Text(message.to_string())).await;
+            }
+            if let Err(e) = self.request_keyframe(session_id).await {
+                debug!("Failed to request a keyframe for session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// End a session, closing the viewer's socket and telling the agent to stop
+    pub async fn end_session(&self, session_id: Uuid) -> Result<Session, String> {
+        self.close_session(session_id, None).await
+    }
+
+    /// End a session the agent could not start, keeping `failure` (its
+    /// reason and pre-flight report) on the record
+    pub async fn fail_session(&self, session_id: Uuid, failure: serde_json::Value) -> Result<Session, String> {
+        self.close_session(session_id, Some(failure)).await
+    }
+
+    async fn close_session(&self, session_id: Uuid, failure: Option<serde_json::Value>) -> Result<Session, String> {
+        let session_conn = self.sessions.write().await.remove(&session_id)
+            .ok_or_else(|| format!("Session not found: {}", session_id))?;
+        info!("Session ended: {}", session_id);
+
+        let mut session = session_conn.session;
+        mark_ended(&mut session);
+        if let Some(failure) = failure {
+            session.status = "failed".to_string();
+            session.metadata.insert(SESSION_FAILURE_METADATA.to_string(), failure);
+        }
+        self.session_tokens.revoke(session_id).await;
+        self.transfers.session_ended(session_id).await;
+        for tx in std::iter::once(&session_conn.tx).chain(session_conn.observers.values()) {
+            let _ = tx.send(Message::Close(None));
+        }
+
+        // Remove session from device's active sessions
+        let mut devices = self.devices.write().await;
+        if let Some(device) = devices.get_mut(&session.agent_id) {
+            device.active_sessions.retain(|&id| id != session_id);
+            let command = serde_json::json!({
+                "type": "SessionEnd",
+                "session_id": session_id.to_string(),
+            });
+            let _ = device.tx.send(Message::Text(command.to_string()));
+        }
+        drop(devices);
+
+        let outcome = self.control.release(session.agent_id, session_id).await;
+        self.apply_control_outcome(session.agent_id, outcome).await;
+        self.idle_tracker.remove(session_id).await;
+        self.quality_monitor.remove(session_id).await;
+        self.chat_tracker.remove(session_id).await;
+        self.relay_manager.remove_route(&session_id.to_string()).await;
+        if let Some(lane) = &self.udp_lane {
+            lane.release(session.agent_id, session_id).await;
+        }
+        self.record_ended_session(session.clone()).await;
+        if let Some(code) = self.support_codes.session_ended(session_id).await {
+            if let Some(agent_id) = code.agent_id {
+                self.close_support_code(agent_id, &code, "session_ended").await;
+            }
+        }
+        let _ = self.broadcast_tx.send(BroadcastMessage::SessionEnded(session_id));
+        Ok(session)
+    }
+
+    async fn record_ended_session(&self, session: Session) {
+        let scope = self.device_scope(session.agent_id).await;
+        self.webhooks.notify(WebhookEvent::SessionEnded, session.organization_id, serde_json::json!({ "session": session })).await;
+        self.live_events.publish(scope, LiveEvent::SessionEnded { session: session.clone() });
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.end_session(&session).await {
+                warn!("Failed to persist end of session {}: {}", session.id, e);
+            }
+        }
+
+        let mut ended = self.ended_sessions.write().await;
+        if ended.len() >= ENDED_SESSION_HISTORY {
+            if let Some(evicted) = ended.pop_front() {
+                self.session_commands.write().await.remove(&evicted.id);
+                self.session_events.write().await.remove(&evicted.id);
+                self.session_notes.forget(evicted.id).await;
+            }
+        }
+        ended.push_back(session);
+    }
+
+    /// Get all connected devices
+    pub async fn get_connected_devices(&self) -> Vec<Agent> {
+        let devices = self.devices.read().await;
+        devices.values().map(|conn| conn.agent.clone()).collect()
+    }
+
+    /// Get a connected or previously connected agent, and whether it is online
+    pub async fn get_agent(&self, agent_id: Uuid) -> Option<(Agent, bool)> {
+        if let Some(connection) = self.devices.read().await.get(&agent_id) {
+            let mut agent = connection.agent.clone();
+            agent.last_seen = Some(connection.last_ping);
+            return Some((agent, true));
+        }
+        self.offline_agents.read().await.get(&agent_id).map(|agent| (agent.clone(), false))
+    }
+
+    /// What a connected agent can do; offline and unknown agents are not
+    /// held back here, as creating their sessions fails anyway
+    pub async fn agent_capabilities(&self, agent_id: Uuid) -> AgentCapabilities {
+        self.devices.read().await
+            .get(&agent_id)
+            .map(|connection| connection.agent.capabilities.0.clone())
+            .unwrap_or_default()
+    }
+
+    /// Connected and offline devices matching `filter`, in no particular order
+    pub async fn list_devices(&self, filter: &DeviceFilter) -> Vec<DeviceListing> {
+        let candidates = self.device_index.read().await.candidates(filter);
+        let devices = self.devices.read().await;
+        let offline_agents = self.offline_agents.read().await;
+        let now = Utc::now();
+
+        let listing = |agent_id: &Uuid| -> Option<DeviceListing> {
+            if let Some(connection) = devices.get(agent_id) {
+                let mut agent = connection.agent.clone();
+                agent.last_seen = Some(connection.last_ping);
+                let access_paused_until = connection.access_paused_until.filter(|&until| until > now);
+                return Some(DeviceListing { agent, online: true, access_paused_until });
+            }
+            offline_agents.get(agent_id).map(|agent| DeviceListing { agent: agent.clone(), online: false, access_paused_until: None })
+        };
+        let listed: Vec<DeviceListing> = match candidates {
+            Some(ids) => ids.iter().filter_map(listing).collect(),
+            None if filter.online == Some(true) => devices.keys().filter_map(listing).collect(),
+            None if filter.online == Some(false) => offline_agents.keys().filter_map(listing).collect(),
+            None => devices.keys().chain(offline_agents.keys()).filter_map(listing).collect(),
+        };
+        listed.into_iter().filter(|device| filter.matches(&device.agent, device.online)).collect()
+    }
+
+    /// Filtering scope of events about `agent_id`
+    async fn device_scope(&self, agent_id: Uuid) -> EventScope {
+        let (group_id, organization_id) = self.get_agent(agent_id).await
+            .map_or((None, None), |(agent, _)| (agent.group_id, agent.organization_id));
+        EventScope::Device { agent_id, group_id, organization_id }
+    }
+
+    /// Devices, active sessions, latest metrics, pending backstage requests
+    /// and relay nodes a new events connection in `tenant` with
+    /// `subscription` starts from
+    pub async fn live_snapshot(&self, tenant: Tenant, subscription: &Subscription) -> LiveSnapshot {
+        let filter = DeviceFilter { tenant, ..Default::default() };
+        let mut devices: Vec<DeviceListing> = self.list_devices(&filter).await
+            .into_iter()
+            .filter(|device| subscription.matches_device(device.agent.id, device.agent.group_id))
+            .collect();
+        device_groups::sort_devices(&mut devices, DeviceSort::Name, SortOrder::Asc);
+        let listed: std::collections::HashSet<Uuid> = devices.iter().map(|device| device.agent.id).collect();
+
+        let sessions = self.sessions.read().await
+            .values()
+            .filter(|conn| listed.contains(&conn.session.agent_id))
+            .map(|conn| conn.session.clone())
+            .collect();
+        let metrics = self.metrics.read().await
+            .iter()
+            .filter(|(agent_id, _)| listed.contains(agent_id))
+            .filter_map(|(agent_id, history)| history.back().map(|sample| (*agent_id, sample.clone())))
+            .collect();
+
+        let approvals = self.approvals.pending(|approval| listed.contains(&approval.agent_id)).await;
+
+        LiveSnapshot {
+            devices,
+            sessions,
+            metrics,
+            approvals,
+            relay_nodes: self.relay_manager.list_relay_nodes().await,
+        }
+    }
+
+    /// Apply `update` to a connected or offline agent, returning the result
+    async fn update_agent(&self, agent_id: Uuid, update: impl FnOnce(&mut Agent)) -> Option<Agent> {
+        let updated = if let Some(connection) = self.devices.write().await.get_mut(&agent_id) {
+            update(&mut connection.agent);
+            connection.agent.clone()
+        } else {
+            let mut offline_agents = self.offline_agents.write().await;
+            let agent = offline_agents.get_mut(&agent_id)?;
+            update(agent);
+            agent.clone()
+        };
+        self.device_index.write().await.insert(&updated);
+        Some(updated)
+    }
+
+    /// Register a relay node and tell dashboards about it
+    pub async fn register_relay_node(&self, registration: NodeRegistration, now: DateTime<Utc>) -> RelayNode {
+        let node = self.relay_manager.register_relay_node(registration, now).await;
+        self.live_events.publish(EventScope::Global, LiveEvent::RelayNodeHealth { node: node.clone() });
+        node
+    }
+
+    /// Record a relay node's heartbeat and tell dashboards its health
+    pub async fn relay_node_heartbeat(&self, heartbeat: NodeHeartbeat, now: DateTime<Utc>) -> Result<RelayNode, BrokerError> {
+        let node = self.relay_manager.heartbeat(heartbeat, now).await?;
+        self.live_events.publish(EventScope::Global, LiveEvent::RelayNodeHealth { node: node.clone() });
+        Ok(node)
+    }
+
+    /// Drain and evict silent relay nodes, telling dashboards which changed;
+    /// returns the evicted nodes
+    pub async fn sweep_relay_nodes(&self, now: DateTime<Utc>) -> Vec<Uuid> {
+        let before: HashMap<Uuid, _> = self.relay_manager.list_relay_nodes().await
+            .into_iter()
+            .map(|node| (node.id, node.status))
+            .collect();
+        let evicted = self.relay_manager.sweep_relay_nodes(now).await;
+        for node in self.relay_manager.list_relay_nodes().await {
+            if before.get(&node.id) != Some(&node.status) {
+                self.live_events.publish(EventScope::Global, LiveEvent::RelayNodeHealth { node });
+            }
+        }
+        for &node_id in &evicted {
+            self.live_events.publish(EventScope::Global, LiveEvent::RelayNodeRemoved { node_id });
+        }
+        evicted
+    }
+
+    /// All organizations with the number of devices in each, by name
+    pub async fn list_organizations(&self) -> Vec<(Organization, usize)> {
+        let index = self.device_index.read().await;
+        let mut organizations: Vec<(Organization, usize)> = self.organizations.read().await
+            .values()
+            .map(|organization| (organization.clone(), index.organization_size(Some(organization.id))))
+            .collect();
+        organizations.sort_by_key(|(organization, _)| organization.name.to_lowercase());
+        organizations
+    }
+
+    /// An organization and the number of devices in it
+    pub async fn get_organization(&self, organization_id: Uuid) -> Option<(Organization, usize)> {
+        let organization = self.organizations.read().await.get(&organization_id)?.clone();
+        let devices = self.device_index.read().await.organization_size(Some(organization_id));
+        Some((organization, devices))
+    }
+
+    pub async fn create_organization(
+        &self,
+        name: &str,
+        slug: &str,
+        settings: HashMap<String, serde_json::Value>,
+    ) -> Result<Organization, OrganizationError> {
+        let name = organizations::normalize_name(name)?;
+        let slug = organizations::normalize_slug(slug)?;
+        let mut all = self.organizations.write().await;
+        if all.values().any(|organization| organization.slug == slug) {
+            return Err(OrganizationError::DuplicateSlug);
+        }
+
+        let now = Utc::now();
+        let organization = Organization {
+            id: Uuid::new_v4(),
+            name,
+            slug,
+            settings: sqlx::types::Json(settings),
+            created_at: now,
+            updated_at: now,
+        };
+        // The slug is unique in the database too, so a failed write is not
+        // left to diverge from memory
+        if let Some(db) = &self.db {
+            db.insert_organization(&organization).await
+                .map_err(|e| OrganizationError::Database(e.to_string()))?;
+        }
+        all.insert(organization.id, organization.clone());
+        info!("Organization created: {} ({})", organization.slug, organization.id);
+        Ok(organization)
+    }
+
+    /// Rename an organization, change its slug or replace its settings
+    pub async fn update_organization(
+        &self,
+        organization_id: Uuid,
+        name: Option<&str>,
+        slug: Option<&str>,
+        settings: Option<HashMap<String, serde_json::Value>>,
+    ) -> Result<Organization, OrganizationError> {
+        let name = name.map(organizations::normalize_name).transpose()?;
+        let slug = slug.map(organizations::normalize_slug).transpose()?;
+        let mut all = self.organizations.write().await;
+        if let Some(slug) = &slug {
+            if all.values().any(|organization| organization.id != organization_id && &organization.slug == slug) {
+                return Err(OrganizationError::DuplicateSlug);
+            }
+        }
+
+        let mut organization = all.get(&organization_id).ok_or(OrganizationError::NotFound)?.clone();
+        if let Some(name) = name {
+            organization.name = name;
+        }
+        if let Some(slug) = slug {
+            organization.slug = slug;
+        }
+        if let Some(settings) = settings {
+            organization.settings = sqlx::types::Json(settings);
+        }
+        organization.updated_at = Utc::now();
+        if let Some(db) = &self.db {
+            db.update_organization(&organization).await
+                .map_err(|e| OrganizationError::Database(e.to_string()))?;
+        }
+        all.insert(organization_id, organization.clone());
+        Ok(organization)
+    }
+
+    /// Delete an organization that has no devices left, along with its
+    /// groups and policies
+    pub async fn delete_organization(&self, organization_id: Uuid) -> Result<Organization, OrganizationError> {
+        let mut all = self.organizations.write().await;
+        if !all.contains_key(&organization_id) {
+            return Err(OrganizationError::NotFound);
+        }
+        let devices = self.device_index.read().await.organization_size(Some(organization_id));
+        if devices > 0 {
+            return Err(OrganizationError::NotEmpty(devices));
+        }
+        // The database drops the groups, policies and memberships itself
+        if let Some(db) = &self.db {
+            db.delete_organization(organization_id).await
+                .map_err(|e| OrganizationError::Database(e.to_string()))?;
+        }
+        let organization = all.remove(&organization_id).ok_or(OrganizationError::NotFound)?;
+        drop(all);
+
+        self.groups.write().await.retain(|_, group| group.organization_id != Some(organization_id));
+        self.policies.write().await.retain(|_, policy| policy.organization_id != Some(organization_id));
+        info!("Organization deleted: {} ({})", organization.slug, organization_id);
+        Ok(organization)
+    }
+
+    /// Groups in `tenant` with the number of devices in each, by name
+    pub async fn list_groups(&self, tenant: Tenant) -> Vec<(DeviceGroup, usize)> {
+        let index = self.device_index.read().await;
+        let mut groups: Vec<(DeviceGroup, usize)> = self.groups.read().await
+            .values()
+            .filter(|group| tenant.includes(group.organization_id))
+            .map(|group| (group.clone(), index.group_members(group.id).len()))
+            .collect();
+        groups.sort_by_key(|(group, _)| group.name.to_lowercase());
+        groups
+    }
+
+    /// A group in `tenant` and its devices
+    pub async fn get_group(&self, tenant: Tenant, group_id: Uuid) -> Option<(DeviceGroup, Vec<Uuid>)> {
+        let group = self.groups.read().await.get(&group_id).filter(|group| tenant.includes(group.organization_id))?.clone();
+        let mut members = self.device_index.read().await.group_members(group_id);
+        members.sort();
+        Some((group, members))
+    }
+
+    /// Add a group to `organization_id`, where its name must be unique
+    pub async fn create_group(
+        &self,
+        organization_id: Option<Uuid>,
+        name: &str,
+        description: Option<String>,
+    ) -> Result<DeviceGroup, GroupError> {
+        let name = device_groups::normalize_group_name(name)?;
+        let mut groups = self.groups.write().await;
+        if groups.values().any(|group| group.organization_id == organization_id && group.name.eq_ignore_ascii_case(&name)) {
+            return Err(GroupError::DuplicateName);
+        }
+
+        let now = Utc::now();
+        let group = DeviceGroup {
+            id: Uuid::new_v4(),
+            organization_id,
+            name,
+            description: description.filter(|description| !description.trim().is_empty()),
+            created_at: now,
+            updated_at: now,
+        };
+        if let Some(db) = &self.db {
+            if let Err(e) = db.insert_device_group(&group).await {
+                warn!("Failed to persist group {}: {}", group.id, e);
+            }
+        }
+        groups.insert(group.id, group.clone());
+        info!("Device group created: {} ({})", group.name, group.id);
+        Ok(group)
+    }
+
+    /// Rename a group or change its description; an empty description clears it
+    pub async fn update_group(
+        &self,
+        tenant: Tenant,
+        group_id: Uuid,
+        name: Option<&str>,
+        description: Option<String>,
+    ) -> Result<DeviceGroup, GroupError> {
+        let name = name.map(device_groups::normalize_group_name).transpose()?;
+        let mut groups = self.groups.write().await;
+        let organization_id = groups.get(&group_id)
+            .filter(|group| tenant.includes(group.organization_id))
+            .ok_or(GroupError::NotFound)?
+            .organization_id;
+        if let Some(name) = &name {
+            let taken = groups.values().any(|group| {
+                group.id != group_id && group.organization_id == organization_id && group.name.eq_ignore_ascii_case(name)
+            });
+            if taken {
+                return Err(GroupError::DuplicateName);
+            }
+        }
+
+        let group = groups.get_mut(&group_id).ok_or(GroupError::NotFound)?;
+        if let Some(name) = name {
+            group.name = name;
+        }
+        if let Some(description) = description {
+            group.description = Some(description).filter(|description| !description.trim().is_empty());
+        }
+        group.updated_at = Utc::now();
+        let group = group.clone();
+        drop(groups);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.update_device_group(&group).await {
+                warn!("Failed to persist group {}: {}", group.id, e);
+            }
+        }
+        Ok(group)
+    }
+
+    /// Delete a group, leaving its devices ungrouped; returns how many there were
+    pub async fn delete_group(&self, tenant: Tenant, group_id: Uuid) -> Result<usize, GroupError> {
+        let mut groups = self.groups.write().await;
+        if !groups.get(&group_id).is_some_and(|group| tenant.includes(group.organization_id)) {
+            return Err(GroupError::NotFound);
+        }
+        let group = groups.remove(&group_id).ok_or(GroupError::NotFound)?;
+        drop(groups);
+        let members = self.device_index.read().await.group_members(group_id);
+        for agent_id in &members {
+            self.update_agent(*agent_id, |agent| agent.group_id = None).await;
+        }
+
+        // The database ungroups the devices itself
+        if let Some(db) = &self.db {
+            if let Err(e) = db.delete_device_group(group_id).await {
+                warn!("Failed to persist deletion of group {}: {}", group_id, e);
+            }
+        }
+        let group_policies: Vec<Uuid> = self.policies.read().await.values()
+            .filter(|policy| policy.scope == PolicyScope::Group && policy.target_id == Some(group_id))
+            .map(|policy| policy.id)
+            .collect();
+        for policy_id in group_policies {
+            let _ = self.delete_policy(Tenant::All, policy_id).await;
+        }
+        for agent_id in &members {
+            self.push_policy(*agent_id).await;
+        }
+        info!("Device group deleted: {} ({}), {} devices ungrouped", group.name, group_id, members.len());
+        Ok(members.len())
+    }
+
+    /// File a device under `group_id` of its own organization, or take it out
+    /// of its group with `None`
+    pub async fn set_device_group(&self, agent_id: Uuid, group_id: Option<Uuid>) -> Result<Agent, GroupError> {
+        if let Some(group_id) = group_id {
+            let (agent, _) = self.get_agent(agent_id).await.ok_or(GroupError::DeviceNotFound)?;
+            let same_organization = self.groups.read().await
+                .get(&group_id)
+                .is_some_and(|group| group.organization_id == agent.organization_id);
+            if !same_organization {
+                return Err(GroupError::NotFound);
+            }
+        }
+        let agent = self.update_agent(agent_id, |agent| agent.group_id = group_id).await
+            .ok_or(GroupError::DeviceNotFound)?;
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.set_agent_group(agent_id, group_id).await {
+                warn!("Failed to persist group of device {}: {}", agent_id, e);
+            }
+        }
+        self.push_policy(agent_id).await;
+        Ok(agent)
+    }
+
+    /// Replace a device's tags
+    pub async fn set_device_tags(&self, agent_id: Uuid, tags: &[String]) -> Result<Agent, GroupError> {
+        let tags = device_groups::normalize_tags(tags)?;
+        let agent = self.update_agent(agent_id, |agent| agent.tags = tags).await
+            .ok_or(GroupError::DeviceNotFound)?;
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.set_agent_tags(agent_id, &agent.tags).await {
+                warn!("Failed to persist tags of device {}: {}", agent_id, e);
+            }
+        }
+        Ok(agent)
+    }
+
+    /// Set or clear the alias of a device, which must be unique
+    pub async fn set_device_alias(&self, agent_id: Uuid, alias: Option<&str>) -> Result<Agent, GroupError> {
+        let alias = alias.map(device_groups::normalize_alias).transpose()?;
+        if let Some(alias) = &alias {
+            if self.find_device_by_alias(alias).await.is_some_and(|owner| owner != agent_id) {
+                return Err(GroupError::DuplicateAlias);
+            }
+        }
+        let agent = self.update_agent(agent_id, |agent| agent.alias = alias).await
+            .ok_or(GroupError::DeviceNotFound)?;
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.set_agent_alias(agent_id, agent.alias.as_deref()).await {
+                warn!("Failed to persist alias of device {}: {}", agent_id, e);
+            }
+        }
+        Ok(agent)
+    }
+
+    /// The connected or offline device going by `alias`
+    pub async fn find_device_by_alias(&self, alias: &str) -> Option<Uuid> {
+        let alias = alias.trim().to_lowercase();
+        let owns = |agent: &Agent| agent.alias.as_deref() == Some(alias.as_str());
+        if let Some(connection) = self.devices.read().await.values().find(|connection| owns(&connection.agent)) {
+            return Some(connection.agent.id);
+        }
+        self.offline_agents.read().await.values().find(|agent| owns(agent)).map(|agent| agent.id)
+    }
+
+    /// How a viewer of `tenant` behind `viewer_nat` reaches the device with
+    /// ID or alias `device`, or `None` if no such device is known to it
+    pub async fn resolve_device(
+        &self,
+        tenant: Tenant,
+        device: &str,
+        viewer_nat: NatType,
+        viewer_tailnet: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Option<Resolution> {
+        let device_id = match Uuid::parse_str(device.trim()) {
+            Ok(id) => id,
+            Err(_) => self.find_device_by_alias(device).await?,
+        };
+        let (agent, _) = self.get_agent(device_id).await?;
+        if !tenant.includes(agent.organization_id) {
+            return None;
+        }
+
+        let mut resolution = self.rendezvous.resolve(device_id, viewer_nat, viewer_tailnet, now).await;
+        resolution.alias = agent.alias;
+        if !resolution.online {
+            resolution.last_seen = agent.last_seen;
+        }
+        if let Some(relay) = &mut resolution.relay {
+            if let Some(node_id) = relay.relay_node {
+                relay.address = self.relay_manager.list_relay_nodes().await
+                    .into_iter()
+                    .find(|node| node.id == node_id)
+                    .map(|node| node.address);
+            }
+        }
+        Some(resolution)
+    }
+
+    /// Policies in `tenant`, broadest scope first
+    pub async fn list_policies(&self, tenant: Tenant) -> Vec<Policy> {
+        let mut policies: Vec<Policy> = self.policies.read().await
+            .values()
+            .filter(|policy| tenant.includes(policy.organization_id))
+            .cloned()
+            .collect();
+        policies.sort_by_key(|policy| (policy.scope.rank(), policy.created_at));
+        policies
+    }
+
+    pub async fn get_policy(&self, tenant: Tenant, policy_id: Uuid) -> Option<Policy> {
+        self.policies.read().await.get(&policy_id).filter(|policy| tenant.includes(policy.organization_id)).cloned()
+    }
+
+    /// Add a policy for a target in `tenant` without one, pushing it to the
+    /// devices it applies to. Group and device targets must exist; organization
+    /// targets are taken as given, and one left unset is the tenant's own.
+    pub async fn create_policy(
+        &self,
+        tenant: Tenant,
+        scope: PolicyScope,
+        target_id: Option<Uuid>,
+        document: PolicyDocument,
+    ) -> Result<Policy, PolicyError> {
+        let document = document.validate()?;
+        let (target_id, organization_id) = match (scope, target_id) {
+            (PolicyScope::Organization, target_id) => {
+                let target_id = target_id.or(tenant.organization_id());
+                if !tenant.includes(target_id) {
+                    return Err(PolicyError::TargetNotFound);
+                }
+                (target_id, target_id)
+            }
+            (PolicyScope::Group, Some(group_id)) => {
+                let organization_id = self.groups.read().await
+                    .get(&group_id)
+                    .filter(|group| tenant.includes(group.organization_id))
+                    .ok_or(PolicyError::TargetNotFound)?
+                    .organization_id;
+                (Some(group_id), organization_id)
+            }
+            (PolicyScope::Device, Some(agent_id)) => {
+                let (agent, _) = self.get_agent(agent_id).await
+                    .filter(|(agent, _)| tenant.includes(agent.organization_id))
+                    .ok_or(PolicyError::TargetNotFound)?;
+                (Some(agent_id), agent.organization_id)
+            }
+            (_, None) => return Err(PolicyError::Invalid("Group and device policies need a target_id".to_string())),
+        };
+
+        let mut policies = self.policies.write().await;
+        if policies.values().any(|policy| policy.scope == scope && policy.target_id == target_id) {
+            return Err(PolicyError::Duplicate);
+        }
+        let now = Utc::now();
+        let policy = Policy {
+            id: Uuid::new_v4(),
+            scope,
+            target_id,
+            organization_id,
+            document: sqlx::types::Json(document),
+            created_at: now,
+            updated_at: now,
+        };
+        if let Some(db) = &self.db {
+            if let Err(e) = db.insert_policy(&policy).await {
+                warn!("Failed to persist policy {}: {}", policy.id, e);
+            }
+        }
+        policies.insert(policy.id, policy.clone());
+        drop(policies);
+
+        info!("Policy created: {} for {:?} {:?}", policy.id, scope, target_id);
+        self.push_policies(&policy).await;
+        Ok(policy)
+    }
+
+    /// Replace a policy's document, pushing the change to the devices it
+    /// applies to
+    pub async fn update_policy(&self, tenant: Tenant, policy_id: Uuid, document: PolicyDocument) -> Result<Policy, PolicyError> {
+        let document = document.validate()?;
+        let mut policies = self.policies.write().await;
+        let policy = policies.get_mut(&policy_id)
+            .filter(|policy| tenant.includes(policy.organization_id))
+            .ok_or(PolicyError::NotFound)?;
+        policy.document = sqlx::types::Json(document);
+        policy.updated_at = Utc::now();
+        let policy = policy.clone();
+        drop(policies);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.update_policy(&policy).await {
+                warn!("Failed to persist policy {}: {}", policy.id, e);
+            }
+        }
+        self.push_policies(&policy).await;
+        Ok(policy)
+    }
+
+    /// Delete a policy, pushing what is left to the devices it applied to
+    pub async fn delete_policy(&self, tenant: Tenant, policy_id: Uuid) -> Result<Policy, PolicyError> {
+        let mut policies = self.policies.write().await;
+        if !policies.get(&policy_id).is_some_and(|policy| tenant.includes(policy.organization_id)) {
+            return Err(PolicyError::NotFound);
+        }
+        let policy = policies.remove(&policy_id).ok_or(PolicyError::NotFound)?;
+        drop(policies);
+        if let Some(db) = &self.db {
+            if let Err(e) = db.delete_policy(policy_id).await {
+                warn!("Failed to persist deletion of policy {}: {}", policy_id, e);
+            }
+        }
+        info!("Policy deleted: {} for {:?} {:?}", policy_id, policy.scope, policy.target_id);
+        self.push_policies(&policy).await;
+        Ok(policy)
+    }
+
+    /// A device's merged policy and the policies it came from, broadest first
+    pub async fn effective_policy(&self, agent_id: Uuid) -> Option<(PolicyDocument, Vec<Uuid>)> {
+        let (agent, _) = self.get_agent(agent_id).await?;
+        Some(policies::effective_policy(self.policies.read().await.values(), &agent))
+    }
+
+    /// Whether a new session on `agent_id` must wait for consent at the
+    /// device; backstage sessions the policy lets through are audited
+    pub async fn requires_consent(&self, agent_id: Uuid, session_id: Uuid, session_type: &str) -> bool {
+        let Some((policy, _)) = self.effective_policy(agent_id).await else { return false };
+        match policy.consent_for(session_type) {
+            Consent::Required => true,
+            Consent::NotRequired => false,
+            Consent::Bypassed => {
+                self.record_audit(
+                    AuditLog::new("session", "consent_bypassed")
+                        .agent(agent_id)
+                        .session(session_id)
+                        .details(serde_json::json!({ "session_type": session_type })),
+                ).await;
+                false
+            }
+        }
+    }
+
+    /// Whether technicians must write a close-out note before ending
+    /// sessions on `agent_id`
+    pub async fn closeout_note_required(&self, agent_id: Uuid) -> bool {
+        self.effective_policy(agent_id).await
+            .is_some_and(|(policy, _)| policy.closeout_note_required == Some(true))
+    }
+
+    /// Send a connected device its effective policy
+    pub async fn push_policy(&self, agent_id: Uuid) {
+        if !self.devices.read().await.contains_key(&agent_id) {
+            return;
+        }
+        let Some((policy, _)) = self.effective_policy(agent_id).await else { return };
+        let update = policies::policy_update(&policy);
+        if let Err(e) = self.send_to_device(agent_id, Message::Text(update.to_string())).await {
+            debug!("Failed to push policy to device {}: {}", agent_id, e);
+        }
+    }
+
+    /// Push effective policies to the connected devices `changed` applies to
+    async fn push_policies(&self, changed: &Policy) {
+        let affected: Vec<Uuid> = self.devices.read().await.values()
+            .filter(|connection| changed.applies_to(&connection.agent))
+            .map(|connection| connection.agent.id)
+            .collect();
+        for agent_id in affected {
+            self.push_policy(agent_id).await;
+        }
+    }
+
+    /// Get an active or recently ended session
+    pub async fn get_session(&self, session_id: Uuid) -> Option<Session> {
+        if let Some(connection) = self.sessions.read().await.get(&session_id) {
+            return Some(connection.session.clone());
+        }
+        self.ended_sessions.read().await.iter().find(|session| session.id == session_id).cloned()
+    }
+
+    /// Link an active or recently ended session to a PSA ticket, or unlink
+    /// it with `None`
+    pub async fn set_ticket_ref(&self, session_id: Uuid, ticket_ref: Option<String>) -> Option<Session> {
+        let updated = {
+            let mut sessions = self.sessions.write().await;
+            match sessions.get_mut(&session_id) {
+                Some(connection) => {
+                    connection.session.ticket_ref = ticket_ref.clone();
+                    Some(connection.session.clone())
+                }
+                None => None,
+            }
+        };
+        let updated = match updated {
+            Some(session) => session,
+            None => {
+                let mut ended = self.ended_sessions.write().await;
+                let session = ended.iter_mut().find(|session| session.id == session_id)?;
+                session.ticket_ref = ticket_ref.clone();
+                session.clone()
+            }
+        };
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.update_session_ticket_ref(session_id, ticket_ref.as_deref()).await {
+                warn!("Failed to store ticket of session {}: {}", session_id, e);
+            }
+        }
+        Some(updated)
+    }
+
+    /// List active and recently ended sessions matching `filter`, newest first
+    pub async fn list_sessions(&self, filter: &SessionFilter) -> Vec<Session> {
+        let mut matching: Vec<Session> = {
+            let sessions = self.sessions.read().await;
+            sessions.values()
+                .map(|conn| &conn.session)
+                .filter(|session| filter.matches(session))
+                .cloned()
+                .collect()
+        };
+        matching.extend(
+            self.ended_sessions.read().await.iter()
+                .filter(|session| filter.matches(session))
+                .cloned(),
+        );
+        matching.sort_by(|a, b| b.started_at.cmp(&a.started_at).then(a.id.cmp(&b.id)));
+        matching
+    }
+
+    /// Get active sessions for a device
+    pub async fn get_device_sessions(&self, agent_id: Uuid) -> Vec<Session> {
+        let sessions = self.sessions.read().await;
+        sessions
+            .values()
+            .filter(|conn| conn.session.agent_id == agent_id)
+            .map(|conn| conn.session.clone())
+            .collect()
+    }
+
+    /// Record the binary envelope version agreed with a device
+    pub async fn set_binary_protocol(&self, agent_id: Uuid, version: u8) {
+        if let Some(connection) = self.devices.write().await.get_mut(&agent_id) {
+            connection.binary_protocol = Some(version);
+        }
+    }
+
+    /// Record whether a device agreed to receive compressed JSON
+    pub async fn set_compression(&self, agent_id: Uuid, enabled: bool) {
+        if let Some(connection) = self.devices.write().await.get_mut(&agent_id) {
+            connection.compression = enabled;
+        }
+    }
+
+    /// Until when the end user of a connected device paused remote access
+    pub async fn access_paused_until(&self, agent_id: Uuid) -> Option<DateTime<Utc>> {
+        self.devices.read().await
+            .get(&agent_id)?
+            .access_paused_until
+            .filter(|&until| until > Utc::now())
+    }
+
+    /// The end user of `agent_id` paused remote access until `until`, or
+    /// resumed it. The device stays online, shown as paused.
+    pub async fn set_access_paused(&self, agent_id: Uuid, until: Option<DateTime<Utc>>) {
+        let listing = {
+            let mut devices = self.devices.write().await;
+            let Some(connection) = devices.get_mut(&agent_id) else {
+                return;
+            };
+            if connection.access_paused_until == until {
+                return;
+            }
+            connection.access_paused_until = until;
+            let mut agent = connection.agent.clone();
+            agent.last_seen = Some(connection.last_ping);
+            DeviceListing { agent, online: true, access_paused_until: until.filter(|&until| until > Utc::now()) }
+        };
+        info!("Device {} remote access {}", agent_id, until.map_or("resumed".to_string(), |until| format!("paused until {}", until)));
+
+        self.record_audit(
+            AuditLog::new("agent", if until.is_some() { "access_paused" } else { "access_resumed" })
+                .actor(agent_id.to_string())
+                .details(serde_json::json!({ "until": until })),
+        ).await;
+        let scope = self.device_scope(agent_id).await;
+        self.live_events.publish(scope, LiveEvent::DeviceOnline { device: listing });
+    }
+
+    /// Binary envelope version of the agent behind a session
+    pub async fn session_binary_protocol(&self, session_id: Uuid) -> Option<u8> {
+        let agent_id = self.sessions.read().await.get(&session_id)?.session.agent_id;
+        self.devices.read().await.get(&agent_id)?.binary_protocol
+    }
+
+    /// Send message to a specific device, waiting for queue space if the
+    /// device is behind on control traffic
+    pub async fn send_to_device(&self, agent_id: Uuid, message: Message) -> Result<(), String> {
+        let (tx, compression) = {
+            let devices = self.devices.read().await;
+            devices.get(&agent_id)
+                .map(|connection| (connection.tx.clone(), connection.compression))
+                .ok_or_else(|| format!("Device not connected: {}", agent_id))?
+        };
+        let priority = outbound::classify(&message);
+        let message = match message {
+            Message::Text(json) if compression => match self.compression_policy.compress_text(&json) {
+                Some(envelope) => Message::Binary(envelope),
+                None => Message::Text(json),
+            },
+            message => message,
+        };
+        tx.send_wait(message, priority).await
+            .map_err(|e| format!("Failed to send message to device: {}", e))
+    }
+
+    /// Send message to a specific session, waiting for queue space if the
+    /// viewer is behind on control traffic
+    pub async fn send_to_session(&self, session_id: Uuid, message: Message) -> Result<(), String> {
+        let (tx, observers) = {
+            let sessions = self.sessions.read().await;
+            sessions.get(&session_id)
+                .map(|connection| (connection.tx.clone(), connection.observers.values().cloned().collect::<Vec<_>>()))
+                .ok_or_else(|| format!("Session not found: {}", session_id))?
+        };
+        let priority = outbound::classify(&message);
+        // Observers are not waited for; a slow one must not hold up the technician
+        for observer in observers {
+            let _ = observer.send_with_priority(message.clone(), priority.clone());
+        }
+        tx.send_wait(message, priority).await
+            .map_err(|e| format!("Failed to send message to session: {}", e))
+    }
+
+    /// Send message to the agent behind a session
+    pub async fn send_to_session_agent(&self, session_id: Uuid, message: Message) -> Result<(), String> {
+        let agent_id = {
+            let sessions = self.sessions.read().await;
+            sessions.get(&session_id)
+                .map(|conn| conn.session.agent_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?
+        };
+        self.send_to_device(agent_id, message).await
     }
 
     /// Broadcast screen frame to all sessions viewing a device
     pub async fn broadcast_screen_frame(&self, agent_id: Uuid, frame_data: Vec<u8>) {
-        let sessions = self.sessions.read().await;
-        for connection in sessions.values() {
+        let mut activated = Vec::new();
+        let mut viewers = 0;
+        let mut sessions = self.sessions.write().await;
+        for connection in sessions.values_mut() {
             if connection.session.agent_id == agent_id &&
                (connection.session.session_type == "view" || connection.session.session_type == "control") {
                 let message = Message::Binary(frame_data.clone());
-                if let Err(e) = connection.tx.send(message) {
+                for observer in connection.observers.values() {
+                    let _ = observer.send_with_priority(message.clone(), MessagePriority::Normal);
+                }
+                if let Err(e) = connection.tx.send_with_priority(message, MessagePriority::Normal) {
                     warn!("Failed to send screen frame to session {}: {}", connection.session.id, e);
+                    continue;
+                }
+                viewers += 1;
+
+                let session = &mut connection.session;
+                session.bytes_transferred += frame_data.len() as i64;
+                session.frames_captured += 1;
+                if session.status == "connecting" {
+                    session.status = "active".to_string();
+                    session.updated_at = Utc::now();
+                    activated.push(session.id);
+                }
+            }
+        }
+        drop(sessions);
+        self.telemetry.frame_relayed(viewers);
+
+        if let Some(db) = &self.db {
+            for session_id in activated {
+                if let Err(e) = db.update_session_status(session_id, "active").await {
+                    warn!("Failed to persist status of session {}: {}", session_id, e);
+                }
+            }
+        }
+
+        let _ = self.broadcast_tx.send(BroadcastMessage::ScreenFrame(agent_id, frame_data));
+    }
+
+    /// Forward input from a session to its device. Pointer motion is held
+    /// for the input window to coalesce; everything else goes out at once,
+    /// behind the motion that came before it.
+    pub async fn forward_input_event(&self, session_id: Uuid, input_data: Vec<u8>) -> Result<(), String> {
+        let mut sessions = self.sessions.write().await;
+        if let Some(session_conn) = sessions.get_mut(&session_id) {
+            // Only allow input for control sessions
+            if session_conn.session.session_type != "control" {
+                return Err("Session does not have control permissions".to_string());
+            }
+
+            // ...and only from the one holding the device's control token
+            let agent_id = session_conn.session.agent_id;
+            if self.control.holder(agent_id).await != Some(session_id) {
+                return Err("Session does not hold input control".to_string());
+            }
+
+            session_conn.session.bytes_transferred += input_data.len() as i64;
+            let input = session_conn.input.clone();
+            drop(sessions);
+            self.idle_tracker.touch(session_id, Utc::now()).await;
+
+            // Send to device
+            let (tx, binary_protocol) = {
+                let devices = self.devices.read().await;
+                devices.get(&agent_id)
+                    .map(|device_conn| (device_conn.tx.clone(), device_conn.binary_protocol))
+                    .ok_or_else(|| "Target device not connected".to_string())?
+            };
+
+            // Agents predating the envelope get the viewer's bytes as they are
+            if binary_protocol.is_none() {
+                tx.send_wait(Message::Binary(input_data.clone()), MessagePriority::High).await
+                    .map_err(|e| format!("Failed to forward input to device: {}", e))?;
+                let _ = self.broadcast_tx.send(BroadcastMessage::InputEvent(agent_id, input_data));
+                return Ok(());
+            }
+
+            let target = InputTarget {
+                session_id,
+                agent_id,
+                tx,
+                quality_monitor: self.quality_monitor.clone(),
+                broadcast_tx: self.broadcast_tx.clone(),
+            };
+
+            let mut coalescer = input.clone().lock_owned().await;
+            let flush = coalescer.push_batch(&input_data)
+                .map_err(|e| format!("Malformed input batch: {}", e))?;
+            match flush {
+                Flush::Now => target.flush(&mut coalescer).await,
+                Flush::After(window) => {
+                    drop(coalescer);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(window).await;
+                        if let Err(e) = target.flush(&mut *input.lock().await).await {
+                            debug!("Dropping coalesced input for session {}: {}", session_id, e);
+                        }
+                    });
+                    Ok(())
+                }
+                Flush::Pending => Ok(()),
+            }
+        } else {
+            Err(format!("Session not found: {}", session_id))
+        }
+    }
+
+    /// Ask the agent behind a session to stream a tar.zst of a directory.
+    ///
+    /// Returns a receiver yielding archive chunks as the agent uploads them.
+    pub async fn start_archive_collection(
+        &self,
+        session_id: Uuid,
+        path: String,
+        include_globs: Vec<String>,
+        max_size: u64,
+    ) -> Result<mpsc::Receiver<Result<Vec<u8>, std::io::Error>>, String> {
+        let agent_id = {
+            let sessions = self.sessions.read().await;
+            sessions.get(&session_id)
+                .map(|conn| conn.session.agent_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?
+        };
+
+        let request_id = Uuid::new_v4();
+        let (tx, rx) = mpsc::channel(32);
+        self.archive_streams.write().await.insert(request_id, tx);
+
+        let command = serde_json::json!({
+            "type": "CollectArchive",
+            "session_id": session_id.to_string(),
+            "request_id": request_id.to_string(),
+            "path": path,
+            "include_globs": include_globs,
+            "max_size": max_size,
+        });
+
+        if let Err(e) = self.send_to_device(agent_id, Message::Text(command.to_string())).await {
+            self.archive_streams.write().await.remove(&request_id);
+            return Err(e);
+        }
+
+        info!("Archive {} requested from device {} ({})", request_id, agent_id, path);
+        Ok(rx)
+    }
+
+    /// Deliver an archive chunk uploaded by an agent
+    pub async fn push_archive_chunk(&self, request_id: Uuid, data: Vec<u8>, last: bool) {
+        let tx = self.archive_streams.read().await.get(&request_id).cloned();
+        let Some(tx) = tx else {
+            debug!("Dropping chunk for unknown archive {}", request_id);
+            return;
+        };
+
+        // Awaiting here applies the browser's backpressure to the agent connection
+        if !data.is_empty() && tx.send(Ok(data)).await.is_err() {
+            warn!("Archive {} download was abandoned", request_id);
+            self.archive_streams.write().await.remove(&request_id);
+            return;
+        }
+
+        if last {
+            self.archive_streams.write().await.remove(&request_id);
+            info!("Archive {} completed", request_id);
+        }
+    }
+
+    /// Abort an archive download with an error reported by the agent
+    pub async fn fail_archive(&self, request_id: Uuid, message: String) {
+        if let Some(tx) = self.archive_streams.write().await.remove(&request_id) {
+            warn!("Archive {} failed: {}", request_id, message);
+            let _ = tx.send(Err(std::io::Error::other(message))).await;
+        }
+    }
+
+    /// Send a registry operation to the agent behind a session and wait for its result.
+    ///
+    /// Returns the agent's `RegistryResult` message, which carries the audit entry
+    /// even when the operation failed.
+    pub async fn send_registry_command(
+        &self,
+        session_id: Uuid,
+        operation: serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let agent_id = {
+            let sessions = self.sessions.read().await;
+            sessions.get(&session_id)
+                .map(|conn| conn.session.agent_id)
+                .ok_or_else(|| format!("Session not found: {}", session_id))?
+        };
+
+        let request_id = Uuid::new_v4();
+        let (tx, rx) = oneshot::channel();
+        self.registry_requests.write().await.insert(request_id, tx);
+
+        let command = serde_json::json!({
+            "type": "RegistryRequest",
+            "session_id": session_id.to_string(),
+            "request_id": request_id.to_string(),
+            "operation": operation,
+        });
+
+        if let Err(e) = self.send_to_device(agent_id, Message::Text(command.to_string())).await {
+            self.registry_requests.write().await.remove(&request_id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(std::time::Duration::from_secs(REGISTRY_TIMEOUT_SECS), rx).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err("Registry request was dropped".to_string()),
+            Err(_) => {
+                self.registry_requests.write().await.remove(&request_id);
+                Err(format!("Agent did not answer registry request within {}s", REGISTRY_TIMEOUT_SECS))
+            }
+        }
+    }
+
+    /// Deliver a registry result reported by an agent
+    pub async fn complete_registry_request(&self, request_id: Uuid, result: serde_json::Value) {
+        match self.registry_requests.write().await.remove(&request_id) {
+            Some(tx) => {
+                let _ = tx.send(result);
+            }
+            None => debug!("Dropping result for unknown registry request {}", request_id),
+        }
+    }
+
+    /// Send a file browser request to the agent behind a session and wait for its `FsResult`.
+    ///
+    /// `command` is the viewer's message; the session and a fresh request ID
+    /// are filled in, so agents never see IDs chosen by a viewer.
+    pub async fn send_fs_command(
+        &self,
+        session_id: Uuid,
+        mut command: serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let agent_id = self.get_active_session(session_id).await?.agent_id;
+
+        let request_id = Uuid::new_v4();
+        let (tx, rx) = oneshot::channel();
+        self.fs_requests.write().await.insert(request_id, tx);
+
+        command["session_id"] = serde_json::Value::String(session_id.to_string());
+        command["request_id"] = serde_json::Value::String(request_id.to_string());
+        if let Err(e) = self.send_to_device(agent_id, Message::Text(command.to_string())).await {
+            self.fs_requests.write().await.remove(&request_id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(std::time::Duration::from_secs(FS_TIMEOUT_SECS), rx).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err("File browser request was dropped".to_string()),
+            Err(_) => {
+                self.fs_requests.write().await.remove(&request_id);
+                Err(format!("Agent did not answer file browser request within {}s", FS_TIMEOUT_SECS))
+            }
+        }
+    }
+
+    /// Deliver an `FsResult` reported by an agent
+    pub async fn complete_fs_request(&self, request_id: Uuid, result: serde_json::Value) {
+        match self.fs_requests.write().await.remove(&request_id) {
+            Some(tx) => {
+                let _ = tx.send(result);
+            }
+            None => debug!("Dropping result for unknown file browser request {}", request_id),
+        }
+    }
+
+    /// Take the agent's answers about a transfer the relay uploads itself
+    pub async fn open_drop_transfer(&self, session_id: Uuid, transfer_id: Uuid) -> mpsc::Receiver<serde_json::Value> {
+        let (tx, rx) = mpsc::channel(8);
+        self.drop_transfers.write().await.insert(transfer_id, DropRoute { session_id, replies: tx });
+        rx
+    }
+
+    pub async fn close_drop_transfer(&self, transfer_id: Uuid) {
+        self.drop_transfers.write().await.remove(&transfer_id);
+    }
+
+    /// Deliver an agent's `FileTransferResume`, `FileTransferComplete` or
+    /// `FileTransferCancel` to the drop it answers; false if it answers a
+    /// transfer of the viewer's own
+    pub async fn deliver_drop_reply(&self, session_id: Uuid, reply: &serde_json::Value) -> bool {
+        let Some(transfer_id) = reply.get("transfer_id").and_then(|v| v.as_str()).and_then(|id| Uuid::parse_str(id).ok()) else {
+            return false;
+        };
+        let tx = match self.drop_transfers.read().await.get(&transfer_id) {
+            Some(route) if route.session_id == session_id => route.replies.clone(),
+            _ => return false,
+        };
+        if tx.send(reply.clone()).await.is_err() {
+            debug!("Drop transfer {} finished before the agent's answer", transfer_id);
+        }
+        true
+    }
+
+    /// Ask for input control on behalf of a session
+    pub async fn request_control(&self, session_id: Uuid) -> Result<(), String> {
+        let session = self.get_active_session(session_id).await?;
+        if session.session_type != "control" {
+            return Err("View-only sessions cannot take control".to_string());
+        }
+        let outcome = self.control
+            .request(session.agent_id, session_id, &self.session_policy, Utc::now())
+            .await;
+        self.apply_control_outcome(session.agent_id, outcome).await;
+        Ok(())
+    }
+
+    /// The holder's answer to a pending takeover request
+    pub async fn answer_control_request(&self, session_id: Uuid, grant: bool) -> Result<(), String> {
+        let session = self.get_active_session(session_id).await?;
+        let outcome = self.control.answer(session.agent_id, session_id, grant).await;
+        self.apply_control_outcome(session.agent_id, outcome).await;
+        Ok(())
+    }
+
+    /// Give up input control, or a pending request for it
+    pub async fn release_control(&self, session_id: Uuid) -> Result<(), String> {
+        let session = self.get_active_session(session_id).await?;
+        let outcome = self.control.release(session.agent_id, session_id).await;
+        self.apply_control_outcome(session.agent_id, outcome).await;
+        Ok(())
+    }
+
+    /// Settle takeover requests their holders left unanswered; returns how many
+    pub async fn expire_control_requests(&self, now: DateTime<Utc>) -> usize {
+        let outcomes = self.control.expire(now, self.session_policy.takeover_timeout).await;
+        let settled = outcomes.len();
+        for (agent_id, outcome) in outcomes {
+            self.apply_control_outcome(agent_id, outcome).await;
+        }
+        settled
+    }
+
+    /// Warn viewers of sessions about to time out and end the ones that did;
+    /// returns how many sessions ended
+    pub async fn expire_idle_sessions(&self, now: DateTime<Utc>) -> usize {
+        let mut ended = 0;
+        for action in self.idle_tracker.sweep(now, &self.idle_policy).await {
+            match action {
+                IdleAction::Warn { session_id, disconnect_at } => {
+                    let warning = serde_json::json!({
+                        "type": "IdleWarning",
+                        "session_id": session_id,
+                        "disconnect_at": disconnect_at,
+                    });
+                    let _ = self.send_to_session(session_id, Message::Text(warning.to_string())).await;
+                }
+                IdleAction::End { session_id } => {
+                    info!("Ending session {} after inactivity", session_id);
+                    let last_activity = self.idle_tracker.last_activity(session_id).await;
+                    let notice = serde_json::json!({
+                        "type": "SessionEnd",
+                        "session_id": session_id,
+                        "reason": "idle_timeout",
+                    });
+                    let _ = self.send_to_session(session_id, Message::Text(notice.to_string())).await;
+                    match self.end_session(session_id).await {
+                        Ok(_) => {
+                            ended += 1;
+                            self.record_audit(
+                                AuditLog::new("session", "idle_timeout")
+                                    .session(session_id)
+                                    .details(serde_json::json!({ "last_activity": last_activity })),
+                            ).await;
+                        }
+                        Err(e) => {
+                            debug!("Idle session {} already gone: {}", session_id, e);
+                            self.idle_tracker.remove(session_id).await;
+                        }
+                    }
                 }
             }
         }
+        ended
+    }
 
-        let _ = self.broadcast_tx.send(BroadcastMessage::ScreenFrame(agent_id, frame_data));
+    /// Degrade devices that missed heartbeats and disconnect those that
+    /// missed too many, then announce the status changes that have settled.
+    /// Returns how many devices were disconnected.
+    pub async fn sweep_liveness(&self, now: DateTime<Utc>) -> usize {
+        let connected: Vec<(Uuid, DateTime<Utc>)> = self.devices.read().await
+            .iter()
+            .map(|(agent_id, connection)| (*agent_id, connection.last_ping))
+            .collect();
+        let debounce = self.liveness_policy.debounce();
+        let mut disconnected = 0;
+        for (agent_id, last_ping) in connected {
+            let thresholds = match self.effective_policy(agent_id).await {
+                Some((policy, _)) => self.liveness_policy.for_device(&policy),
+                None => self.liveness_policy.clone(),
+            };
+            match thresholds.status_at(last_ping, now) {
+                DeviceStatus::Offline => {
+                    let missed = thresholds.missed(last_ping, now);
+                    info!("Device {} missed {} heartbeats, closing its connection", agent_id, missed);
+                    if let Some(connection) = self.devices.read().await.get(&agent_id) {
+                        let _ = connection.tx.send(Message::Close(Some(CloseFrame {
+                            code: liveness::CLOSE_HEARTBEAT_TIMEOUT,
+                            reason: "heartbeat timeout".into(),
+                        })));
+                    }
+                    self.disconnect(agent_id, now).await;
+                    disconnected += 1;
+                    self.record_audit(
+                        AuditLog::new("agent", "heartbeat_timeout")
+                            .agent(agent_id)
+                            .details(serde_json::json!({ "last_heartbeat": last_ping, "missed_heartbeats": missed })),
+                    ).await;
+                }
+                status => {
+                    if let Some(transition) = self.liveness.observe(agent_id, status, now, debounce).await {
+                        self.announce_status(transition).await;
+                    }
+                }
+            }
+        }
+        for transition in self.liveness.settle(now, debounce).await {
+            self.announce_status(transition).await;
+        }
+        disconnected
     }
 
-    /// Forward input event from session to device
-    pub async fn forward_input_event(&self, session_id: Uuid, input_data: Vec<u8>) -> Result<(), String> {
-        let sessions = self.sessions.read().await;
-        if let Some(session_conn) = sessions.get(&session_id) {
-            // Only allow input for control sessions
-            if session_conn.session.session_type != "control" {
-                return Err("Session does not have control permissions".to_string());
+    /// Announce a device's status without waiting for it to settle
+    async fn announce_now(&self, agent_id: Uuid, status: DeviceStatus) {
+        if let Some(transition) = self.liveness.announce(agent_id, status, Utc::now()).await {
+            self.announce_status(transition).await;
+        }
+    }
+
+    /// Tell the events socket and webhooks about a settled status change,
+    /// and keep it on the device while it is connected
+    async fn announce_status(&self, transition: Transition) {
+        let Transition { agent_id, from, to } = transition;
+        if to != DeviceStatus::Offline {
+            let mut devices = self.devices.write().await;
+            if let Some(connection) = devices.get_mut(&agent_id) {
+                connection.agent.status = to.as_str().to_string();
+                let last_ping = connection.last_ping;
+                drop(devices);
+                if let Some(db) = &self.db {
+                    if let Err(e) = db.update_agent_status(agent_id, to.as_str(), last_ping).await {
+                        warn!("Failed to persist status of device {}: {}", agent_id, e);
+                    }
+                }
             }
+        }
+        let Some((agent, online)) = self.get_agent(agent_id).await else {
+            return;
+        };
+        debug!("Device {} went from {} to {}", agent_id, from.as_str(), to.as_str());
 
-            let agent_id = session_conn.session.agent_id;
-            drop(sessions);
+        let mut data = webhooks::device_data(&agent);
+        data["status"] = serde_json::json!(to);
+        data["previous_status"] = serde_json::json!(from);
+        let event = match to {
+            DeviceStatus::Online => WebhookEvent::DeviceOnline,
+            DeviceStatus::Degraded => WebhookEvent::DeviceDegraded,
+            DeviceStatus::Offline => WebhookEvent::DeviceOffline,
+        };
+        self.webhooks.notify(event, agent.organization_id, data).await;
 
-            // Send to device
-            let devices = self.devices.read().await;
-            if let Some(device_conn) = devices.get(&agent_id) {
-                let message = Message::Binary(input_data.clone());
-                device_conn.tx.send(message)
-                    .map_err(|_| "Failed to forward input to device".to_string())?;
-                
-                let _ = self.broadcast_tx.send(BroadcastMessage::InputEvent(agent_id, input_data));
-                Ok(())
-            } else {
-                Err("Target device not connected".to_string())
+        let scope = EventScope::Device { agent_id, group_id: agent.group_id, organization_id: agent.organization_id };
+        let event = match to {
+            DeviceStatus::Online => {
+                let access_paused_until = self.access_paused_until(agent_id).await;
+                LiveEvent::DeviceOnline { device: DeviceListing { agent, online, access_paused_until } }
             }
-        } else {
-            Err(format!("Session not found: {}", session_id))
+            DeviceStatus::Degraded => LiveEvent::DeviceDegraded { device_id: agent_id, last_seen: agent.last_seen },
+            DeviceStatus::Offline => LiveEvent::DeviceOffline { device_id: agent_id, last_seen: agent.last_seen },
+        };
+        self.live_events.publish(scope, event);
+    }
+
+    /// Revoke access grant `grant_id` of `tenant` and end the sessions its
+    /// guest has open; returns the grant and the sessions ended
+    pub async fn revoke_access_grant(
+        &self,
+        tenant: Tenant,
+        grant_id: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<(AccessGrant, Vec<Uuid>), GrantError> {
+        let grant = self.access_grants.revoke(tenant, grant_id, now).await?;
+        let mut ended = Vec::new();
+        for grant in self.access_grants.take_ended(now).await {
+            let sessions = self.end_grant_sessions(&grant, "grant_revoked").await;
+            if grant.id == grant_id {
+                ended = sessions;
+            }
+        }
+        Ok((grant, ended))
+    }
+
+    /// End the sessions of access grants that expired; returns how many
+    pub async fn expire_access_grants(&self, now: DateTime<Utc>) -> usize {
+        let mut ended = 0;
+        for grant in self.access_grants.take_ended(now).await {
+            let reason = if grant.revoked_at.is_some() { "grant_revoked" } else { "grant_expired" };
+            ended += self.end_grant_sessions(&grant, reason).await.len();
+        }
+        ended
+    }
+
+    /// End the sessions still open under an access grant that ended, telling
+    /// the viewer why
+    async fn end_grant_sessions(&self, grant: &AccessGrant, reason: &str) -> Vec<Uuid> {
+        let mut ended = Vec::new();
+        for &session_id in &grant.sessions {
+            if self.get_active_session(session_id).await.is_err() {
+                continue;
+            }
+            info!("Ending session {} of access grant {}: {}", session_id, grant.id, reason);
+            let notice = serde_json::json!({
+                "type": "SessionEnd",
+                "session_id": session_id,
+                "reason": reason,
+            });
+            let _ = self.send_to_session(session_id, Message::Text(notice.to_string())).await;
+            if self.end_session(session_id).await.is_ok() {
+                ended.push(session_id);
+                self.record_audit(
+                    AuditLog::new("session", reason)
+                        .actor(grant.id.to_string())
+                        .agent(grant.agent_id)
+                        .session(session_id)
+                        .details(serde_json::json!({ "access_grant_id": grant.id })),
+                ).await;
+            }
+        }
+        ended
+    }
+
+    /// A session that is still running
+    pub async fn get_active_session(&self, session_id: Uuid) -> Result<Session, String> {
+        self.sessions.read().await.get(&session_id)
+            .map(|connection| connection.session.clone())
+            .ok_or_else(|| format!("Session not found: {}", session_id))
+    }
+
+    /// Tell the sessions involved, and the agent, what a control command changed
+    async fn apply_control_outcome(&self, agent_id: Uuid, outcome: ControlOutcome) {
+        match outcome {
+            ControlOutcome::Transferred { from, to } => {
+                info!("Control of device {} moved from {:?} to {:?}", agent_id, from, to);
+                let message = Message::Text(control_changed(agent_id, from, to).to_string());
+                let viewers: Vec<OutboundSender> = self.sessions.read().await.values()
+                    .filter(|connection| connection.session.agent_id == agent_id)
+                    .flat_map(|connection| std::iter::once(&connection.tx).chain(connection.observers.values()).cloned())
+                    .collect();
+                for tx in viewers {
+                    let _ = tx.send(message.clone());
+                }
+                let agent_message = serde_json::json!({ "type": "ControlChanged", "holder": to });
+                if let Err(e) = self.send_to_device(agent_id, Message::Text(agent_message.to_string())).await {
+                    debug!("Failed to tell device {} about control change: {}", agent_id, e);
+                }
+
+                let mut entry = AuditLog::new("session", "control_transferred")
+                    .details(serde_json::json!({ "agent_id": agent_id, "from": from, "to": to }));
+                if let Some(session_id) = to.or(from) {
+                    entry = entry.session(session_id);
+                }
+                self.record_audit(entry).await;
+            }
+            ControlOutcome::Requested { holder, request } => {
+                let user_id = self.get_session(request.requester).await.map(|session| session.user_id);
+                let prompt = serde_json::json!({
+                    "type": "ControlRequested",
+                    "session_id": request.requester,
+                    "user_id": user_id,
+                    "expires_at": request.expires_at,
+                });
+                let _ = self.send_to_session(holder, Message::Text(prompt.to_string())).await;
+                let pending = serde_json::json!({
+                    "type": "ControlPending",
+                    "holder": holder,
+                    "expires_at": request.expires_at,
+                });
+                let _ = self.send_to_session(request.requester, Message::Text(pending.to_string())).await;
+            }
+            ControlOutcome::Denied { requester, holder, reason } => {
+                let denied = serde_json::json!({
+                    "type": "ControlDenied",
+                    "holder": holder,
+                    "reason": reason,
+                });
+                let _ = self.send_to_session(requester, Message::Text(denied.to_string())).await;
+            }
+            ControlOutcome::Withdrawn { requester, holder } => {
+                let withdrawn = serde_json::json!({
+                    "type": "ControlRequestWithdrawn",
+                    "session_id": requester,
+                });
+                let _ = self.send_to_session(holder, Message::Text(withdrawn.to_string())).await;
+            }
+            ControlOutcome::Unchanged => {}
+        }
+    }
+
+    /// Append an entry to the audit trail, with secrets redacted from its details.
+    ///
+    /// Entries go to the database when one is configured, otherwise to the
+    /// audit file; without either they are only logged.
+    pub async fn record_audit(&self, mut entry: AuditLog) {
+        debug!("Audit {}:{} by {:?}", entry.category, entry.action, entry.actor);
+        audit::redact(&mut entry.details.0);
+        let result = match (&self.db, &self.audit_file) {
+            (Some(db), _) => db.append_audit_log(&entry).await,
+            (None, Some(file)) => file.append(&entry).await.map_err(Into::into),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to persist audit entry {}:{}: {}", entry.category, entry.action, e);
+        }
+    }
+
+    /// A page of the audit trail matching `filter`, newest first, and how many entries match
+    pub async fn query_audit(&self, filter: &AuditFilter, limit: usize, offset: usize) -> anyhow::Result<(Vec<AuditLog>, usize)> {
+        if let Some(db) = &self.db {
+            let (entries, total) = db.query_audit_log(filter, limit as i64, offset as i64).await?;
+            return Ok((entries, total as usize));
         }
+        let Some(file) = &self.audit_file else {
+            return Ok((Vec::new(), 0));
+        };
+        let matching = file.query(filter).await?;
+        let total = matching.len();
+        Ok((matching.into_iter().skip(offset).take(limit).collect(), total))
     }
 
-    /// Get device statistics
-    pub async fn get_stats(&self) -> DeviceManagerStats {
+    /// Device statistics for `tenant`; rate limiting counts are server-wide
+    pub async fn get_stats(&self, tenant: Tenant) -> DeviceManagerStats {
         let devices = self.devices.read().await;
         let sessions = self.sessions.read().await;
+        let devices: HashMap<&Uuid, &DeviceConnection> = devices.iter()
+            .filter(|(_, conn)| tenant.includes(conn.agent.organization_id))
+            .collect();
+        let sessions: HashMap<&Uuid, &SessionConnection> = sessions.iter()
+            .filter(|(_, conn)| tenant.includes(conn.session.organization_id))
+            .collect();
 
         DeviceManagerStats {
             connected_devices: devices.len(),
             active_sessions: sessions.len(),
+            sessions_by_type: sessions.values()
+                .fold(HashMap::new(), |mut acc, conn| {
+                    *acc.entry(conn.session.session_type.clone()).or_insert(0) += 1;
+                    acc
+                }),
             devices_by_platform: devices.values()
                 .map(|conn| conn.agent.platform.clone())
                 .fold(HashMap::new(), |mut acc, platform| {
                     *acc.entry(platform).or_insert(0) += 1;
                     acc
                 }),
+            outbound_queues: devices.iter()
+                .map(|(id, conn)| (format!("device:{}", id), conn.tx.stats()))
+                .chain(sessions.iter().map(|(id, conn)| (format!("session:{}", id), conn.tx.stats())))
+                .collect(),
+            rate_limiting: self.rate_limiter.stats(),
         }
     }
 }
@@ -417,11 +3576,703 @@ impl DeviceManager {
 pub struct DeviceManagerStats {
     pub connected_devices: usize,
     pub active_sessions: usize,
+    pub sessions_by_type: HashMap<String, usize>,
     pub devices_by_platform: HashMap<String, usize>,
+    /// Outbound queue depth and drop counters per relay connection
+    pub outbound_queues: HashMap<String, OutboundStats>,
+    /// Throttled requests, lockouts and WebSocket counts
+    pub rate_limiting: RateLimitStats,
 }
 
 impl Default for DeviceManager {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{fixtures, test_database};
+    use crate::relay::outbound::testing::{texts, texts_of};
+
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        let Some(db) = test_database().await else { return };
+        let db = Arc::new(db);
+        let user_id = fixtures::user(&db).await;
+
+        let manager = DeviceManager::new().with_database(db.clone());
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-03".to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, outbound::channel().0).await.unwrap();
+        let request = |agent_id| SessionRequest { agent_id, session_type: SessionType::View, user_id, capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let ended = manager.create_session(request(agent_id), outbound::channel().0).await.unwrap();
+        let open = manager.create_session(request(agent_id), outbound::channel().0).await.unwrap();
+        manager.end_session(ended).await.unwrap();
+        let command = |command: &str| SessionCommand {
+            id: Uuid::new_v4(),
+            command: command.to_string(),
+            stdout: "ok\n".to_string(),
+            stderr: String::new(),
+            exit_code: Some(0),
+            execution_time: Utc::now(),
+            duration_ms: 5,
+            timeout_seconds: 10,
+            max_length: 8192,
+            shell: None,
+            executed_by: "tech@example.com".to_string(),
+            truncated: false,
+            timed_out: false,
+            cancelled: false,
+        };
+        let ended_session = manager.get_session(ended).await.unwrap();
+        let first = command("whoami");
+        assert!(manager.record_session_command(&ended_session, user_id, first.clone()).await);
+        let note = SessionEvent::new(ended, crate::session_events::SessionEventKind::Note, "tech@example.com", serde_json::json!({ "content": "checked logs" }));
+        assert_eq!(manager.record_session_events(&ended_session, user_id, vec![note.clone()]).await.len(), 1);
+        drop(manager);
+
+        // The open session and the connection are lost with the old process
+        let restarted = DeviceManager::new().with_database(db.clone());
+        restarted.restore_from_database(&db).await.unwrap();
+
+        let (agent, online) = restarted.get_agent(agent_id).await.unwrap();
+        assert!(!online);
+        assert_eq!(agent.status, "offline");
+        assert_eq!(restarted.get_session(ended).await.unwrap().status, "ended");
+        assert_eq!(restarted.get_session(open).await.unwrap().status, "failed");
+        assert_eq!(db.get_agent_by_id(agent_id).await.unwrap().unwrap().status, "offline");
+
+        // Command history continues where the old process left it
+        assert_eq!(restarted.session_commands(ended).await, vec![first.clone()]);
+        assert!(!restarted.record_session_command(&ended_session, user_id, first.clone()).await);
+        let second = command("hostname");
+        assert!(restarted.record_session_command(&ended_session, user_id, second.clone()).await);
+        assert_eq!(restarted.session_commands(ended).await, vec![first, second]);
+        assert!(restarted.record_session_events(&ended_session, user_id, vec![note.clone()]).await.is_empty());
+        assert_eq!(restarted.session_events(ended, None).await, vec![note]);
+    }
+
+    #[tokio::test]
+    async fn test_deregister_takes_device_offline() {
+        let manager = DeviceManager::new();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-05".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, outbound::channel().0).await.unwrap();
+        let request = SessionRequest { agent_id, session_type: SessionType::View, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = manager.create_session(request, outbound::channel().0).await.unwrap();
+        let registered_at = manager.get_agent(agent_id).await.unwrap().0.last_seen.unwrap();
+
+        manager.deregister_device(agent_id, "shutdown").await.unwrap();
+
+        let (agent, online) = manager.get_agent(agent_id).await.unwrap();
+        assert!(!online);
+        assert_eq!(agent.status, "offline");
+        assert!(agent.last_seen.unwrap() >= registered_at);
+        assert_eq!(manager.get_session(session_id).await.unwrap().status, "ended");
+
+        let mut broadcasts = Vec::new();
+        while let Ok(message) = manager.broadcast_rx.write().await.try_recv() {
+            broadcasts.push(message);
+        }
+        assert!(matches!(broadcasts.last(), Some(BroadcastMessage::DeviceDisconnected(id)) if *id == agent_id));
+        assert!(broadcasts.iter().any(|message| matches!(message, BroadcastMessage::SessionEnded(id) if *id == session_id)));
+
+        // A second deregister, e.g. one racing the socket close, finds nothing
+        assert!(manager.deregister_device(agent_id, "shutdown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_live_events_follow_device_and_sessions() {
+        use crate::live_events::Delivery;
+
+        let manager = DeviceManager::new()
+            .with_liveness_policy(LivenessPolicy { debounce_secs: 0, ..Default::default() });
+        let mailbox = manager.live_events.subscribe(Tenant::All, Subscription::default());
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-06".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, outbound::channel().0).await.unwrap();
+        let request = SessionRequest { agent_id, session_type: SessionType::View, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = manager.create_session(request, outbound::channel().0).await.unwrap();
+
+        let snapshot = manager.live_snapshot(Tenant::All, &Subscription::default()).await;
+        assert_eq!(snapshot.devices.len(), 1);
+        assert_eq!(snapshot.sessions[0].id, session_id);
+
+        let drain = || {
+            let mut events = Vec::new();
+            while let Some(Delivery::Event(event)) = mailbox.take() {
+                events.push(*event);
+            }
+            events
+        };
+        let events = drain();
+        assert!(matches!(&events[0], LiveEvent::DeviceOnline { device } if device.agent.id == agent_id && device.online));
+        assert!(matches!(&events[1], LiveEvent::SessionStarted { session } if session.id == session_id));
+
+        manager.deregister_device(agent_id, "shutdown").await.unwrap();
+
+        let events = drain();
+        assert!(matches!(&events[0], LiveEvent::SessionEnded { session } if session.id == session_id));
+        assert!(matches!(&events[1], LiveEvent::DeviceOffline { device_id, .. } if *device_id == agent_id));
+    }
+
+    #[tokio::test]
+    async fn test_paused_device_refuses_sessions() {
+        let manager = Arc::new(DeviceManager::new());
+        let mailbox = manager.live_events.subscribe(Tenant::All, Subscription::default());
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-07".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, outbound::channel().0).await.unwrap();
+        let request = || SessionRequest { agent_id, session_type: SessionType::Console, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        while mailbox.take().is_some() {}
+
+        // The agent reports the end user's pause; the device stays online
+        let until = Utc::now() + chrono::Duration::hours(2);
+        let paused = serde_json::json!({ "type": "AccessPaused", "until": until });
+        crate::relay::handle_agent_message(&manager, &agent_id.to_string(), Message::Text(paused.to_string())).await.unwrap();
+        assert_eq!(manager.create_session(request(), outbound::channel().0).await, Err(SessionCreateError::Paused(agent_id, until)));
+        let listed = manager.list_devices(&DeviceFilter::default()).await;
+        assert!(listed[0].online);
+        assert_eq!(listed[0].access_paused_until, Some(until));
+        assert_eq!(serde_json::to_value(&listed[0]).unwrap()["access_paused_until"], serde_json::json!(until));
+        let events: Vec<_> = std::iter::from_fn(|| mailbox.take()).collect();
+        assert!(matches!(
+            &events[..],
+            [crate::live_events::Delivery::Event(event)]
+                if matches!(&**event, LiveEvent::DeviceOnline { device } if device.access_paused_until == Some(until))
+        ));
+        let response = SessionCreateError::Paused(agent_id, until).into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let resumed = serde_json::json!({ "type": "AccessPaused", "until": null });
+        crate::relay::handle_agent_message(&manager, &agent_id.to_string(), Message::Text(resumed.to_string())).await.unwrap();
+        assert!(manager.create_session(request(), outbound::channel().0).await.is_ok());
+        assert!(serde_json::to_value(&manager.list_devices(&DeviceFilter::default()).await[0]).unwrap().get("access_paused_until").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_session_keeps_why() {
+        let manager = DeviceManager::new();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-06".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, outbound::channel().0).await.unwrap();
+        let request = SessionRequest { agent_id, session_type: SessionType::Control, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = manager.create_session(request, outbound::channel().0).await.unwrap();
+
+        let failure = serde_json::json!({
+            "reason": "Screen capture permission denied by Portal",
+            "readiness": { "checks": [{ "check": "capture", "passed": false, "diagnostic": "Screen capture permission denied by Portal", "duration_ms": 4 }] },
+        });
+        let session = manager.fail_session(session_id, failure.clone()).await.unwrap();
+        assert_eq!(session.status, "failed");
+        assert!(session.ended_at.is_some());
+        assert_eq!(session.metadata.get(SESSION_FAILURE_METADATA), Some(&failure));
+        assert!(manager.get_active_session(session_id).await.is_err());
+        assert!(manager.fail_session(session_id, failure).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_viewer_joining_stream_requests_keyframe() {
+        let manager = DeviceManager::new();
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-06".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, agent_tx).await.unwrap();
+        let request = || SessionRequest { agent_id, session_type: SessionType::View, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let keyframe_requests = || async {
+            let mut requested = Vec::new();
+            while let Ok(Some(Message::Text(text))) =
+                tokio::time::timeout(std::time::Duration::from_millis(50), agent_rx.recv()).await
+            {
+                let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+                if frame["type"] == "RequestKeyframe" {
+                    requested.push(frame["session_id"].as_str().unwrap().to_string());
+                }
+            }
+            requested
+        };
+
+        // Nothing is streaming yet, so the first viewer waits for the first frame
+        let (viewer_tx, _viewer_rx) = outbound::channel();
+        let first = manager.create_session(request(), viewer_tx.clone()).await.unwrap();
+        manager.attach_session_channel(first, viewer_tx.clone()).await;
+        assert!(keyframe_requests().await.is_empty());
+
+        manager.broadcast_screen_frame(agent_id, vec![0; 100]).await;
+        let second = manager.create_session(request(), viewer_tx.clone()).await.unwrap();
+        manager.attach_session_channel(second, viewer_tx).await;
+        assert_eq!(keyframe_requests().await, [second.to_string()]);
+
+        manager.request_keyframe(first).await.unwrap();
+        assert_eq!(keyframe_requests().await, [first.to_string()]);
+        assert!(manager.request_keyframe(Uuid::new_v4()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_policy_changes_reach_the_device() {
+        let manager = DeviceManager::new();
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "till-02".to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, agent_tx).await.unwrap();
+        let pushed = || async {
+            let mut policies = Vec::new();
+            while let Ok(Some(Message::Text(text))) =
+                tokio::time::timeout(std::time::Duration::from_millis(50), agent_rx.recv()).await
+            {
+                let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+                if frame["type"] == "PolicyUpdate" {
+                    policies.push(frame["policy"].clone());
+                }
+            }
+            policies
+        };
+
+        let group = manager.create_group(None, "Tills", None).await.unwrap();
+        let document = PolicyDocument {
+            max_fps: Some(15),
+            allowed_session_types: Some(vec!["view".to_string()]),
+            ..Default::default()
+        };
+        let policy = manager.create_policy(Tenant::All, PolicyScope::Group, Some(group.id), document).await.unwrap();
+        assert!(pushed().await.is_empty());
+
+        // Joining the group brings its policy along
+        manager.set_device_group(agent_id, Some(group.id)).await.unwrap();
+        assert_eq!(pushed().await, [serde_json::json!({ "max_fps": 15, "allowed_session_types": ["view"] })]);
+        let request = |session_type| SessionRequest { agent_id, session_type, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let refused = manager.create_session(request(SessionType::Control), outbound::channel().0).await;
+        assert!(refused.unwrap_err().to_string().contains("does not allow control sessions"));
+        assert!(manager.create_session(request(SessionType::View), outbound::channel().0).await.is_ok());
+
+        // The device's own policy wins over its group's
+        let own = manager.create_policy(Tenant::All, PolicyScope::Device, Some(agent_id), PolicyDocument {
+            max_fps: Some(5),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(pushed().await, [serde_json::json!({ "max_fps": 5, "allowed_session_types": ["view"] })]);
+        let (_, sources) = manager.effective_policy(agent_id).await.unwrap();
+        assert_eq!(sources, [policy.id, own.id]);
+        assert_eq!(
+            manager.create_policy(Tenant::All, PolicyScope::Device, Some(agent_id), PolicyDocument::default()).await.unwrap_err(),
+            PolicyError::Duplicate
+        );
+
+        // Deleting the group takes its policy with it
+        manager.delete_group(Tenant::All, group.id).await.unwrap();
+        assert_eq!(pushed().await, [serde_json::json!({ "max_fps": 5 })]);
+        assert_eq!(manager.get_policy(Tenant::All, policy.id).await.map(|p| p.id), None);
+        assert_eq!(
+            manager.create_policy(Tenant::All, PolicyScope::Group, Some(group.id), PolicyDocument::default()).await.unwrap_err(),
+            PolicyError::TargetNotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cursor_updates_follow_the_viewer_toggle() {
+        let manager = DeviceManager::new();
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-07".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, agent_tx).await.unwrap();
+        let request = SessionRequest { agent_id, session_type: SessionType::View, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        let session_id = manager.create_session(request, viewer_tx.clone()).await.unwrap();
+        manager.attach_session_channel(session_id, viewer_tx).await;
+        texts(&viewer_rx).await;
+
+        // Cursor streaming is on until the viewer says otherwise
+        let update = serde_json::json!({
+            "type": "CursorUpdate",
+            "session_id": session_id.to_string(),
+            "cursor": { "x": 10, "y": 20, "visible": true },
+        });
+        assert_eq!(manager.relay_cursor_update(session_id, update.clone()).await, Ok(true));
+        assert_eq!(texts(&viewer_rx).await, vec![update.clone()]);
+
+        manager.set_cursor_streaming(session_id, false).await.unwrap();
+        let toggle = texts(&agent_rx).await.into_iter().find(|text| text["type"] == "SetCursorStreaming").unwrap();
+        assert_eq!(toggle["enabled"], false);
+        assert_eq!(manager.get_session(session_id).await.unwrap().settings["cursor_streaming"], false);
+
+        // Updates the agent sent before it heard of the toggle are dropped
+        assert_eq!(manager.relay_cursor_update(session_id, update.clone()).await, Ok(false));
+        assert!(texts(&viewer_rx).await.is_empty());
+
+        manager.set_cursor_streaming(session_id, true).await.unwrap();
+        assert_eq!(manager.relay_cursor_update(session_id, update).await, Ok(true));
+        assert!(manager.relay_cursor_update(Uuid::new_v4(), serde_json::json!({})).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_input_mode_switch_waits_for_the_agent() {
+        let manager = DeviceManager::new();
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "cad-01".to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, agent_tx).await.unwrap();
+        let request = |session_type| SessionRequest { agent_id, session_type, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        let session_id = manager.create_session(request(SessionType::Control), viewer_tx.clone()).await.unwrap();
+        manager.attach_session_channel(session_id, viewer_tx).await;
+        let mode = || async { manager.get_session(session_id).await.unwrap().settings.get(INPUT_MODE_SETTING).cloned() };
+
+        // Asking only reaches the agent; nothing changes until it answers
+        manager.set_input_mode(session_id, InputMode::Relative).await.unwrap();
+        let asked = texts_of(&agent_rx, "SetInputMode").await;
+        assert_eq!(asked.len(), 1);
+        assert_eq!((asked[0]["session_id"].as_str(), asked[0]["mode"].as_str()), (Some(session_id.to_string().as_str()), Some("relative")));
+        assert_eq!(mode().await, None);
+        assert!(texts_of(&viewer_rx, "InputModeChanged").await.is_empty());
+
+        manager.confirm_input_mode(session_id, InputMode::Relative, None).await.unwrap();
+        assert_eq!(mode().await, Some(serde_json::json!("relative")));
+        let confirmed = texts_of(&viewer_rx, "InputModeChanged").await;
+        assert_eq!((confirmed[0]["mode"].as_str(), confirmed[0]["reason"].is_null()), (Some("relative"), true));
+
+        // An agent that cannot inject movement stays absolute and says why
+        manager.confirm_input_mode(session_id, InputMode::Absolute, Some("unsupported")).await.unwrap();
+        assert_eq!(mode().await, Some(serde_json::json!("absolute")));
+        assert_eq!(texts_of(&viewer_rx, "InputModeChanged").await[0]["reason"], "unsupported");
+
+        // Only control sessions drive the pointer
+        let viewer = manager.create_session(request(SessionType::View), outbound::channel().0).await.unwrap();
+        assert!(manager.set_input_mode(viewer, InputMode::Relative).await.is_err());
+        assert!(manager.set_input_mode(Uuid::new_v4(), InputMode::Relative).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_input_preview_waits_for_the_agent() {
+        let manager = DeviceManager::new();
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "training-01".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, agent_tx).await.unwrap();
+        let request = |session_type| SessionRequest { agent_id, session_type, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        let session_id = manager.create_session(request(SessionType::Control), viewer_tx.clone()).await.unwrap();
+        manager.attach_session_channel(session_id, viewer_tx).await;
+        let preview = || async { manager.get_session(session_id).await.unwrap().settings.get(INPUT_PREVIEW_SETTING).cloned() };
+
+        manager.set_input_preview(session_id, true).await.unwrap();
+        assert_eq!(texts_of(&agent_rx, "SetInputPreview").await[0]["enabled"], true);
+        assert_eq!(preview().await, None);
+        assert!(texts_of(&viewer_rx, "InputPreviewChanged").await.is_empty());
+
+        manager.confirm_input_preview(session_id, true).await.unwrap();
+        assert_eq!(preview().await, Some(serde_json::json!(true)));
+        assert_eq!(texts_of(&viewer_rx, "InputPreviewChanged").await[0]["enabled"], true);
+
+        let viewer = manager.create_session(request(SessionType::View), outbound::channel().0).await.unwrap();
+        assert!(manager.set_input_preview(viewer, true).await.is_err());
+        assert!(manager.confirm_input_preview(Uuid::new_v4(), false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_cap_is_held_to_the_policy_ceiling() {
+        let manager = DeviceManager::new();
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "field-laptop".to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, agent_tx).await.unwrap();
+        let request = |bandwidth_kbps| SessionRequest {
+            agent_id,
+            session_type: SessionType::View,
+            user_id: Uuid::new_v4(),
+            capabilities: None,
+            bandwidth_kbps,
+            compress_frames: false,
+            ticket_ref: None,
+        };
+        async fn last(rx: &outbound::OutboundReceiver, kind: &str) -> Option<serde_json::Value> {
+            let mut last = None;
+            while let Ok(Some(message)) = tokio::time::timeout(std::time::Duration::from_millis(50), rx.recv()).await {
+                if let Message::Text(text) = message {
+                    let text: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    if text["type"] == kind {
+                        last = Some(text);
+                    }
+                }
+            }
+            last
+        }
+
+        // Without a policy the requested cap stands
+        let session_id = manager.create_session(request(Some(2000)), outbound::channel().0).await.unwrap();
+        assert_eq!(manager.bandwidth_cap(session_id).await, Some(2000));
+
+        manager.create_policy(Tenant::All, PolicyScope::Device, Some(agent_id), PolicyDocument {
+            max_bandwidth_kbps: Some(500),
+            ..Default::default()
+        }).await.unwrap();
+
+        // New sessions get the ceiling whether or not they ask for a cap
+        let capped = manager.create_session(request(Some(2000)), outbound::channel().0).await.unwrap();
+        assert_eq!(manager.bandwidth_cap(capped).await, Some(500));
+        let uncapped = manager.create_session(request(None), outbound::channel().0).await.unwrap();
+        assert_eq!(manager.bandwidth_cap(uncapped).await, Some(500));
+
+        // Live changes reach the agent and the viewer, held to the ceiling too
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        manager.attach_session_channel(session_id, viewer_tx).await;
+        assert_eq!(manager.set_bandwidth_cap(session_id, Some(300)).await, Ok(Some(300)));
+        assert_eq!(last(&agent_rx, "SetBandwidthCap").await.unwrap()["kbps"], 300);
+        assert_eq!(manager.set_bandwidth_cap(session_id, None).await, Ok(Some(500)));
+        assert_eq!(manager.bandwidth_cap(session_id).await, Some(500));
+        let changed = last(&viewer_rx, "BandwidthCapChanged").await.unwrap();
+        assert_eq!((changed["kbps"].as_u64(), changed["ceiling_kbps"].as_u64()), (Some(500), Some(500)));
+
+        assert!(manager.set_bandwidth_cap(Uuid::new_v4(), Some(300)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_input_is_coalesced_before_reaching_the_device() {
+        use crate::relay::input_coalescer::InputCounters;
+        use crate::relay::protocol::{decode_input_batch, encode_input_batch, InputEventBody};
+
+        let manager = DeviceManager::new().with_input_window(std::time::Duration::from_millis(20));
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-08".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, agent_tx).await.unwrap();
+        manager.set_binary_protocol(agent_id, 1).await;
+        let request = SessionRequest { agent_id, session_type: SessionType::Control, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = manager.create_session(request, outbound::channel().0).await.unwrap();
+        manager.quality_monitor.next_probe(session_id).await;
+        let batch = |events: &[(&str, serde_json::Value)]| {
+            let data: Vec<Vec<u8>> = events.iter().map(|(_, event)| serde_json::to_vec(event).unwrap()).collect();
+            let bodies: Vec<InputEventBody<'_>> = events
+                .iter()
+                .zip(&data)
+                .map(|((event_type, _), data)| InputEventBody { event_type, data })
+                .collect();
+            encode_input_batch(&bodies)
+        };
+        let forwarded = || async {
+            let mut batches = Vec::new();
+            while let Ok(Some(message)) =
+                tokio::time::timeout(std::time::Duration::from_millis(50), agent_rx.recv()).await
+            {
+                let Message::Binary(data) = message else { continue };
+                let envelope = Envelope::decode(&data).unwrap();
+                assert_eq!((envelope.kind, envelope.session_id), (MessageKind::InputBatch, session_id));
+                let events: Vec<String> = decode_input_batch(envelope.payload)
+                    .unwrap()
+                    .into_iter()
+                    .map(|event| String::from_utf8(event.data.to_vec()).unwrap())
+                    .collect();
+                batches.push(events);
+            }
+            batches
+        };
+        let mouse_move = |x: i32| ("mouse_move", serde_json::json!({ "type": "MouseMove", "x": x, "y": 0 }));
+        let release = ("mouse_button", serde_json::json!({ "type": "MouseButton", "button": "Left", "pressed": false }));
+
+        // Moves within the window go out once, at the last position
+        for x in 1..=10 {
+            manager.forward_input_event(session_id, batch(&[mouse_move(x)])).await.unwrap();
+        }
+        assert_eq!(forwarded().await, [[r#"{"type":"MouseMove","x":10,"y":0}"#]]);
+
+        // A button goes out at once, behind the motion before it
+        manager.forward_input_event(session_id, batch(&[mouse_move(11), mouse_move(12), release])).await.unwrap();
+        assert_eq!(forwarded().await, [[
+            r#"{"type":"MouseMove","x":12,"y":0}"#,
+            r#"{"button":"Left","pressed":false,"type":"MouseButton"}"#,
+        ]]);
+        let quality = manager.quality_monitor.quality(session_id).await.unwrap();
+        assert_eq!(quality.input, InputCounters { events_in: 13, events_out: 3, batches: 2 });
+        assert!(manager.forward_input_event(session_id, vec![1]).await.is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_metrics_deserialize() {
+        // Heartbeat data as the agent sends it, including fields the relay ignores
+        let data = serde_json::json!({
+            "timestamp": 1_700_000_000u64,
+            "agent_id": "agent-1",
+            "uptime": 3600,
+            "active_sessions": 1,
+            "cpu_usage": 42.5,
+            "memory_used": 1024,
+            "memory_total": 4096,
+            "disk_used": 10,
+            "disk_total": 100,
+            "load_average": [1.0, 0.5, 0.25],
+            "update": {"status": "failed", "version": "1.3.0", "error": "new version did not come up"},
+            "resources": {"cpu_percent": 48.5, "cpu_budget_percent": 50, "memory_used": 1048576, "frames_throttled": 12}
+        });
+        let metrics: DeviceMetrics = serde_json::from_value(data).unwrap();
+        assert_eq!(metrics.cpu_usage, 42.5);
+        assert_eq!(metrics.load_average, Some([1.0, 0.5, 0.25]));
+        assert_eq!(metrics.active_sessions, 1);
+        assert_eq!(metrics.update.as_ref().unwrap().status, "failed");
+        let resources = metrics.resources.as_ref().unwrap();
+        assert_eq!((resources.cpu_budget_percent, resources.frames_throttled), (Some(50), 12));
+        assert_eq!(resources.memory_limit, None);
+
+        // Older agents send none of the metrics
+        let legacy: DeviceMetrics = serde_json::from_value(serde_json::json!({"agent_id": "agent-1"})).unwrap();
+        assert_eq!(legacy, DeviceMetrics::default());
+
+        let sample = MetricsSample { recorded_at: Utc::now(), metrics };
+        let json = serde_json::to_value(&sample).unwrap();
+        assert_eq!(json["memory_total"], 4096);
+        assert!(json["recorded_at"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_history_evicts_oldest() {
+        let manager = DeviceManager::new();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-04".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = manager.register_device(registration, outbound::channel().0).await.unwrap();
+        assert!(manager.latest_metrics(agent_id).await.is_none());
+
+        let start = Utc::now();
+        for active_sessions in 0..METRICS_HISTORY as u32 + 5 {
+            let metrics = DeviceMetrics { active_sessions, ..Default::default() };
+            manager.record_metrics(agent_id, metrics).await.unwrap();
+        }
+
+        let history = manager.metrics_history(agent_id, start).await;
+        assert_eq!(history.len(), METRICS_HISTORY);
+        assert_eq!(history[0].metrics.active_sessions, 5);
+        assert_eq!(manager.latest_metrics(agent_id).await.unwrap().metrics.active_sessions, METRICS_HISTORY as u32 + 4);
+        assert!(manager.metrics_history(agent_id, Utc::now() + chrono::Duration::seconds(1)).await.is_empty());
+
+        // Unknown devices have nothing to record against
+        assert!(manager.record_metrics(Uuid::new_v4(), DeviceMetrics::default()).await.is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Fields organizations define for their devices
+//!
+//! A field has a key values are set by, a label the dashboard shows and a
+//! type every value is checked against before it is stored: free text, a
+//! number, a `YYYY-MM-DD` date, or one of the options of an enum.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as DbJson;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use super::RecordError;
+
+/// Most fields one organization may define
+pub const MAX_FIELDS: usize = 100;
+
+/// Longest field key, in characters
+const MAX_KEY_LENGTH: usize = 64;
+
+/// Longest field label or enum option, in characters
+const MAX_LABEL_LENGTH: usize = 255;
+
+/// Most options an enum field may have
+const MAX_OPTIONS: usize = 100;
+
+/// Longest text value, in characters
+pub const MAX_TEXT_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "VARCHAR", rename_all = "snake_case")]
+pub enum FieldType {
+    Text,
+    Number,
+    /// A calendar date, `YYYY-MM-DD`
+    Date,
+    /// One of the field's options
+    Enum,
+}
+
+/// A field an organization defined
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct CustomField {
+    pub id: Uuid,
+    pub organization_id: Option<Uuid>,
+    /// Name values are set by, e.g. `asset_tag`
+    pub key: String,
+    pub label: String,
+    pub field_type: FieldType,
+    /// Values an enum field may take, in display order; empty for other types
+    pub options: DbJson<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A field as an admin defines it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldDefinition {
+    pub key: String,
+    pub label: String,
+    pub field_type: FieldType,
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// Changes to a field; its key and type stay as they are
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldUpdate {
+    pub label: Option<String>,
+    pub options: Option<Vec<String>>,
+}
+
+/// Key trimmed and lowercased, or why it can't be used
+pub fn normalize_key(key: &str) -> Result<String, RecordError> {
+    let key = key.trim().to_lowercase();
+    let valid = (1..=MAX_KEY_LENGTH).contains(&key.len())
+        && key.starts_with(|c: char| c.is_ascii_lowercase())
+        && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        return Err(RecordError::Invalid(format!(
+            "Invalid field key '{}': keys are up to {} letters, digits and underscores, starting with a letter",
+            key, MAX_KEY_LENGTH
+        )));
+    }
+    Ok(key)
+}
+
+/// Trimmed label, or why it can't be used
+pub fn normalize_label(label: &str) -> Result<String, RecordError> {
+    let label = label.trim();
+    if label.is_empty() || label.chars().count() > MAX_LABEL_LENGTH {
+        return Err(RecordError::Invalid(format!("Field labels have 1 to {} characters", MAX_LABEL_LENGTH)));
+    }
+    Ok(label.to_string())
+}
+
+/// Trimmed options of a field of `field_type`, or why they can't be used;
+/// only enum fields have options, and they must have at least one
+pub fn normalize_options(field_type: FieldType, options: &[String]) -> Result<Vec<String>, RecordError> {
+    if field_type != FieldType::Enum {
+        if !options.is_empty() {
+            return Err(RecordError::Invalid("Only enum fields have options".to_string()));
+        }
+        return Ok(Vec::new());
+    }
+    if options.is_empty() || options.len() > MAX_OPTIONS {
+        return Err(RecordError::Invalid(format!("Enum fields have 1 to {} options", MAX_OPTIONS)));
+    }
+    let mut normalized: Vec<String> = Vec::with_capacity(options.len());
+    for option in options {
+        let option = option.trim();
+        if option.is_empty() || option.chars().count() > MAX_LABEL_LENGTH {
+            return Err(RecordError::Invalid(format!("Enum options have 1 to {} characters", MAX_LABEL_LENGTH)));
+        }
+        if normalized.iter().any(|existing| existing.eq_ignore_ascii_case(option)) {
+            return Err(RecordError::Invalid(format!("Option '{}' is listed twice", option)));
+        }
+        normalized.push(option.to_string());
+    }
+    Ok(normalized)
+}
+
+impl FieldDefinition {
+    /// The definition with its key, label and options normalized
+    pub fn validate(self) -> Result<Self, RecordError> {
+        Ok(Self {
+            key: normalize_key(&self.key)?,
+            label: normalize_label(&self.label)?,
+            options: normalize_options(self.field_type, &self.options)?,
+            field_type: self.field_type,
+        })
+    }
+}
+
+impl CustomField {
+    /// `value` as it is stored for this field, or why it doesn't fit.
+    /// Text is trimmed, dates are written `YYYY-MM-DD` and enum values take
+    /// the spelling of their option.
+    pub fn validate_value(&self, value: &serde_json::Value) -> Result<serde_json::Value, RecordError> {
+        let invalid = |expected: &str| RecordError::Invalid(format!(
+            "Field '{}' takes {}", self.key, expected
+        ));
+        match self.field_type {
+            FieldType::Text => {
+                let text = value.as_str().ok_or_else(|| invalid("text"))?.trim();
+                if text.chars().count() > MAX_TEXT_LENGTH {
+                    return Err(invalid(&format!("at most {} characters", MAX_TEXT_LENGTH)));
+                }
+                Ok(text.into())
+            }
+            FieldType::Number => match value {
+                serde_json::Value::Number(_) => Ok(value.clone()),
+                _ => Err(invalid("a number")),
+            },
+            FieldType::Date => {
+                let date = value.as_str()
+                    .and_then(|date| NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok())
+                    .ok_or_else(|| invalid("a date, YYYY-MM-DD"))?;
+                Ok(date.format("%Y-%m-%d").to_string().into())
+            }
+            FieldType::Enum => {
+                let choice = value.as_str().map(str::trim).unwrap_or_default();
+                self.options.iter()
+                    .find(|option| option.eq_ignore_ascii_case(choice))
+                    .map(|option| option.as_str().into())
+                    .ok_or_else(|| invalid(&format!("one of: {}", self.options.join(", "))))
+            }
+        }
+    }
+}
+
+/// A field's value on one device
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct FieldValue {
+    pub agent_id: Uuid,
+    pub field_id: Uuid,
+    pub value: DbJson<serde_json::Value>,
+    pub updated_by: Option<Uuid>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(field_type: FieldType, options: &[&str]) -> CustomField {
+        CustomField {
+            id: Uuid::new_v4(),
+            organization_id: None,
+            key: "asset".to_string(),
+            label: "Asset".to_string(),
+            field_type,
+            options: DbJson(options.iter().map(|option| option.to_string()).collect()),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_key() {
+        assert_eq!(normalize_key(" Asset_Tag2 ").unwrap(), "asset_tag2");
+        for key in ["", "2nd_owner", "asset-tag", "asset tag", &"a".repeat(65)] {
+            assert!(normalize_key(key).is_err(), "{:?} was accepted", key);
+        }
+    }
+
+    #[test]
+    fn test_definition_options() {
+        let definition = FieldDefinition {
+            key: "Site".to_string(),
+            label: " Site ".to_string(),
+            field_type: FieldType::Enum,
+            options: vec![" Berlin".to_string(), "Paris".to_string()],
+        }.validate().unwrap();
+        assert_eq!(definition.key, "site");
+        assert_eq!(definition.label, "Site");
+        assert_eq!(definition.options, ["Berlin", "Paris"]);
+
+        assert!(normalize_options(FieldType::Enum, &[]).is_err());
+        assert!(normalize_options(FieldType::Enum, &["a".to_string(), "A".to_string()]).is_err());
+        assert!(normalize_options(FieldType::Text, &["a".to_string()]).is_err());
+        assert!(normalize_options(FieldType::Number, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_text_values() {
+        let text = field(FieldType::Text, &[]);
+        assert_eq!(text.validate_value(&json!("  rack 4 ")).unwrap(), json!("rack 4"));
+        assert!(text.validate_value(&json!(4)).is_err());
+        assert!(text.validate_value(&json!("x".repeat(MAX_TEXT_LENGTH + 1))).is_err());
+    }
+
+    #[test]
+    fn test_number_values() {
+        let number = field(FieldType::Number, &[]);
+        assert_eq!(number.validate_value(&json!(12.5)).unwrap(), json!(12.5));
+        assert_eq!(number.validate_value(&json!(-3)).unwrap(), json!(-3));
+        assert!(number.validate_value(&json!("12")).is_err());
+        assert!(number.validate_value(&json!(true)).is_err());
+    }
+
+    #[test]
+    fn test_date_values() {
+        let date = field(FieldType::Date, &[]);
+        assert_eq!(date.validate_value(&json!(" 2026-02-28")).unwrap(), json!("2026-02-28"));
+        for invalid in [json!("2026-02-30"), json!("28.02.2026"), json!("2026-02-28T10:00:00Z"), json!(20260228)] {
+            assert!(date.validate_value(&invalid).is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn test_enum_values() {
+        let site = field(FieldType::Enum, &["Berlin", "Paris"]);
+        assert_eq!(site.validate_value(&json!("paris")).unwrap(), json!("Paris"));
+        let error = site.validate_value(&json!("London")).unwrap_err();
+        assert_eq!(error, RecordError::Invalid("Field 'asset' takes one of: Berlin, Paris".to_string()));
+        assert!(site.validate_value(&json!(1)).is_err());
+    }
+}
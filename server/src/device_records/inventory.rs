@@ -0,0 +1,258 @@
+//! Hardware and software inventory agents report
+//!
+//! Agents send an `Inventory` message when they register and once a day
+//! after. The relay keeps the latest report of each device and, from the
+//! second report on, records every value that differs from the one before,
+//! so an admin can see when a disk was swapped or the OS was upgraded.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as DbJson;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::models::NetworkInterface;
+
+/// Most adapters or disks kept from one report
+const MAX_ENTRIES: usize = 64;
+
+/// Longest string kept from a report, in characters
+const MAX_VALUE_LENGTH: usize = 256;
+
+/// What an agent reports about its machine
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Inventory {
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    /// Kernel version, or the build number on Windows
+    pub os_build: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    /// SMBIOS system UUID
+    pub system_uuid: Option<String>,
+    /// User logged on at the console, if any
+    pub logged_in_user: Option<String>,
+    pub cpu: Option<String>,
+    pub cpu_cores: Option<u32>,
+    /// Installed memory, in bytes
+    pub memory_total: Option<u64>,
+    pub interfaces: Vec<NetworkInterface>,
+    pub disks: Vec<DiskInventory>,
+    pub agent_version: Option<String>,
+}
+
+/// A disk as the agent reports it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiskInventory {
+    pub mount_point: String,
+    pub name: Option<String>,
+    pub file_system: Option<String>,
+    /// Capacity, in bytes
+    pub total_bytes: u64,
+}
+
+/// Latest inventory of a device
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct DeviceInventory {
+    pub agent_id: Uuid,
+    pub inventory: DbJson<Inventory>,
+    /// When the relay received the report
+    pub collected_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A value that differs between two reports. `field` names an inventory
+/// field, or an adapter or disk as `interfaces.<name>` or
+/// `disks.<mount point>`; a value that is absent on one side is null.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct InventoryChange {
+    pub id: Uuid,
+    pub agent_id: Uuid,
+    pub field: String,
+    pub previous: DbJson<serde_json::Value>,
+    pub current: DbJson<serde_json::Value>,
+    pub changed_at: DateTime<Utc>,
+}
+
+impl Inventory {
+    /// The report cut down to what the relay keeps, adapters sorted by name
+    /// and disks by mount point
+    pub fn sanitized(mut self) -> Self {
+        for value in [
+            &mut self.os_name,
+            &mut self.os_version,
+            &mut self.os_build,
+            &mut self.manufacturer,
+            &mut self.model,
+            &mut self.serial_number,
+            &mut self.system_uuid,
+            &mut self.logged_in_user,
+            &mut self.cpu,
+            &mut self.agent_version,
+        ] {
+            *value = value.take().map(|text| truncate(text.trim())).filter(|text| !text.is_empty());
+        }
+        self.interfaces.truncate(MAX_ENTRIES);
+        for interface in &mut self.interfaces {
+            interface.name = truncate(&interface.name);
+            interface.ipv4.truncate(MAX_ENTRIES);
+            interface.ipv6.truncate(MAX_ENTRIES);
+        }
+        self.interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+        self.disks.truncate(MAX_ENTRIES);
+        for disk in &mut self.disks {
+            disk.mount_point = truncate(&disk.mount_point);
+        }
+        self.disks.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+        self
+    }
+}
+
+fn truncate(text: &str) -> String {
+    text.chars().take(MAX_VALUE_LENGTH).collect()
+}
+
+/// Values that differ from `previous` to `current`, as field name, previous
+/// value and current value. Adapters are matched by name and disks by mount
+/// point, so one that was added, removed or changed is one change. Changes
+/// are sorted by field.
+pub fn diff(previous: &Inventory, current: &Inventory) -> Vec<(String, serde_json::Value, serde_json::Value)> {
+    let as_map = |inventory: &Inventory| match serde_json::to_value(inventory) {
+        Ok(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    let (previous_map, current_map) = (as_map(previous), as_map(current));
+
+    let mut changes = Vec::new();
+    for (field, current_value) in &current_map {
+        let previous_value = previous_map.get(field).unwrap_or(&serde_json::Value::Null);
+        match field.as_str() {
+            "interfaces" => diff_entries(field, previous_value, current_value, "name", &mut changes),
+            "disks" => diff_entries(field, previous_value, current_value, "mount_point", &mut changes),
+            _ if previous_value != current_value => {
+                changes.push((field.clone(), previous_value.clone(), current_value.clone()));
+            }
+            _ => {}
+        }
+    }
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
+}
+
+/// Changes between two lists of objects, matched by their `key` member
+fn diff_entries(
+    field: &str,
+    previous: &serde_json::Value,
+    current: &serde_json::Value,
+    key: &str,
+    changes: &mut Vec<(String, serde_json::Value, serde_json::Value)>,
+) {
+    let entries = |list: &serde_json::Value| -> Vec<(String, serde_json::Value)> {
+        list.as_array()
+            .map(|entries| entries.iter()
+                .map(|entry| (entry[key].as_str().unwrap_or_default().to_string(), entry.clone()))
+                .collect())
+            .unwrap_or_default()
+    };
+    let (previous, current) = (entries(previous), entries(current));
+    let find = |list: &[(String, serde_json::Value)], name: &str| {
+        list.iter().find(|(other, _)| other == name).map(|(_, entry)| entry.clone())
+    };
+
+    for (name, entry) in &current {
+        match find(&previous, name) {
+            Some(before) if before == *entry => {}
+            before => changes.push((format!("{}.{}", field, name), before.unwrap_or_default(), entry.clone())),
+        }
+    }
+    for (name, entry) in &previous {
+        if find(&current, name).is_none() {
+            changes.push((format!("{}.{}", field, name), entry.clone(), serde_json::Value::Null));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn inventory() -> Inventory {
+        Inventory {
+            os_name: Some("Windows".to_string()),
+            os_version: Some("11".to_string()),
+            os_build: Some("22631".to_string()),
+            serial_number: Some("5CG1234XYZ".to_string()),
+            memory_total: Some(16 << 30),
+            interfaces: vec![NetworkInterface {
+                name: "Ethernet".to_string(),
+                mac: Some("aa:bb:cc:dd:ee:ff".to_string()),
+                ipv4: vec!["192.168.1.20/24".to_string()],
+                ipv6: vec!["2001:db8::20/64".to_string()],
+            }],
+            disks: vec![DiskInventory {
+                mount_point: "C:\\".to_string(),
+                name: Some("System".to_string()),
+                file_system: Some("NTFS".to_string()),
+                total_bytes: 512 << 30,
+            }],
+            agent_version: Some("0.1.0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unchanged_inventory_has_no_changes() {
+        assert!(diff(&inventory(), &inventory()).is_empty());
+    }
+
+    #[test]
+    fn test_scalar_changes() {
+        let mut upgraded = inventory();
+        upgraded.os_build = Some("26100".to_string());
+        upgraded.memory_total = Some(32 << 30);
+        upgraded.logged_in_user = Some("ACME\\jdoe".to_string());
+
+        let changes = diff(&inventory(), &upgraded);
+        assert_eq!(changes, vec![
+            ("logged_in_user".to_string(), json!(null), json!("ACME\\jdoe")),
+            ("memory_total".to_string(), json!(16u64 << 30), json!(32u64 << 30)),
+            ("os_build".to_string(), json!("22631"), json!("26100")),
+        ]);
+    }
+
+    #[test]
+    fn test_disk_and_interface_changes() {
+        let mut current = inventory();
+        current.disks[0].total_bytes = 1 << 40;
+        current.disks.push(DiskInventory { mount_point: "D:\\".to_string(), total_bytes: 2 << 40, ..Default::default() });
+        current.interfaces.clear();
+
+        let changes = diff(&inventory(), &current);
+        let fields: Vec<_> = changes.iter().map(|(field, _, _)| field.as_str()).collect();
+        assert_eq!(fields, ["disks.C:\\", "disks.D:\\", "interfaces.Ethernet"]);
+        assert_eq!(changes[0].1["total_bytes"], json!(512u64 << 30));
+        assert_eq!(changes[0].2["total_bytes"], json!(1u64 << 40));
+        assert_eq!(changes[1].1, json!(null));
+        assert_eq!(changes[2].2, json!(null));
+    }
+
+    #[test]
+    fn test_sanitized() {
+        let mut report = inventory();
+        report.model = Some("  ".to_string());
+        report.cpu = Some("x".repeat(1000));
+        report.disks = (0..100).rev()
+            .map(|i| DiskInventory { mount_point: format!("/mnt/{:03}", i), ..Default::default() })
+            .collect();
+
+        let report = report.sanitized();
+        assert_eq!(report.model, None);
+        assert_eq!(report.cpu.unwrap().len(), MAX_VALUE_LENGTH);
+        assert_eq!(report.disks.len(), MAX_ENTRIES);
+        assert!(report.disks.windows(2).all(|pair| pair[0].mount_point < pair[1].mount_point));
+    }
+}
@@ -0,0 +1,726 @@
+//! Notes, custom fields and inventory kept per device
+//!
+//! Technicians keep a markdown note on each device; every save is a
+//! revision with its author and time, and the newest is the current note.
+//! Admins define [`custom_fields`] for their organization, which
+//! technicians fill in per device. Agents report their [`inventory`] when
+//! they register and daily after, unless a policy turns it off
+//! (`inventory_enabled`). The device detail API returns all three.
+
+pub mod custom_fields;
+pub mod inventory;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as DbJson;
+use sqlx::FromRow;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::{authz, jwt::{error, AuthUser}};
+use crate::database::DatabaseService;
+use crate::models::{Agent, AuditLog};
+use crate::organizations::{self, Tenant};
+use crate::AppState;
+
+pub use custom_fields::{CustomField, FieldDefinition, FieldUpdate, FieldValue};
+pub use inventory::{DeviceInventory, Inventory, InventoryChange};
+
+/// Longest note, in bytes
+pub const MAX_NOTE_LENGTH: usize = 64 * 1024;
+
+/// Revisions kept of each device's note, the current one included
+const MAX_NOTE_REVISIONS: usize = 100;
+
+/// Inventory changes kept per device
+const MAX_INVENTORY_CHANGES: usize = 500;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecordError {
+    /// No custom field with this ID in the caller's organization
+    NotFound,
+    /// Another field of the organization already has this key
+    DuplicateKey,
+    Invalid(String),
+}
+
+impl IntoResponse for RecordError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            RecordError::NotFound => (StatusCode::NOT_FOUND, "Custom field not found".to_string()),
+            RecordError::DuplicateKey => (StatusCode::CONFLICT, "A field with this key already exists".to_string()),
+            RecordError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        error(status, message)
+    }
+}
+
+/// One revision of a device's note
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct DeviceNote {
+    pub id: Uuid,
+    pub agent_id: Uuid,
+    /// Markdown, rendered by the dashboard
+    pub body: String,
+    pub author_id: Option<Uuid>,
+    pub author_email: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A custom field with its value on one device, if it has one
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceField {
+    #[serde(flatten)]
+    pub field: CustomField,
+    pub value: Option<serde_json::Value>,
+    pub updated_by: Option<Uuid>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteRequest {
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FieldValuesRequest {
+    /// Values by field key; null clears a field
+    pub values: HashMap<String, serde_json::Value>,
+}
+
+/// Notes, custom fields and inventory of every device
+pub struct DeviceRecords {
+    db: Option<Arc<DatabaseService>>,
+    /// Note revisions per device, oldest first
+    notes: RwLock<HashMap<Uuid, Vec<DeviceNote>>>,
+    fields: RwLock<HashMap<Uuid, CustomField>>,
+    /// Field values per device, by field ID
+    values: RwLock<HashMap<Uuid, HashMap<Uuid, FieldValue>>>,
+    inventory: RwLock<HashMap<Uuid, DeviceInventory>>,
+    /// Inventory changes per device, oldest first
+    inventory_changes: RwLock<HashMap<Uuid, VecDeque<InventoryChange>>>,
+}
+
+impl Default for DeviceRecords {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceRecords {
+    pub fn new() -> Self {
+        Self {
+            db: None,
+            notes: RwLock::new(HashMap::new()),
+            fields: RwLock::new(HashMap::new()),
+            values: RwLock::new(HashMap::new()),
+            inventory: RwLock::new(HashMap::new()),
+            inventory_changes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Keep records in `db`
+    pub fn with_database(mut self, db: Arc<DatabaseService>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Load the records a previous run stored
+    pub async fn initialize(&self) -> Result<(), String> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let load = |what: &str, e: anyhow::Error| format!("Failed to load {}: {}", what, e);
+
+        let notes = db.list_device_notes().await.map_err(|e| load("device notes", e))?;
+        let mut by_device: HashMap<Uuid, Vec<DeviceNote>> = HashMap::new();
+        for note in notes {
+            by_device.entry(note.agent_id).or_default().push(note);
+        }
+        *self.notes.write().await = by_device;
+
+        let fields = db.list_custom_fields().await.map_err(|e| load("custom fields", e))?;
+        info!("Loaded {} custom fields", fields.len());
+        *self.fields.write().await = fields.into_iter().map(|field| (field.id, field)).collect();
+
+        let values = db.list_device_field_values().await.map_err(|e| load("custom field values", e))?;
+        let mut by_device: HashMap<Uuid, HashMap<Uuid, FieldValue>> = HashMap::new();
+        for value in values {
+            by_device.entry(value.agent_id).or_default().insert(value.field_id, value);
+        }
+        *self.values.write().await = by_device;
+
+        let inventory = db.list_device_inventory().await.map_err(|e| load("device inventory", e))?;
+        *self.inventory.write().await = inventory.into_iter().map(|inventory| (inventory.agent_id, inventory)).collect();
+
+        let changes = db.list_inventory_changes().await.map_err(|e| load("inventory changes", e))?;
+        let mut by_device: HashMap<Uuid, VecDeque<InventoryChange>> = HashMap::new();
+        for change in changes {
+            by_device.entry(change.agent_id).or_default().push_back(change);
+        }
+        *self.inventory_changes.write().await = by_device;
+        Ok(())
+    }
+
+    /// Current note of a device
+    pub async fn note(&self, agent_id: Uuid) -> Option<DeviceNote> {
+        self.notes.read().await.get(&agent_id).and_then(|notes| notes.last().cloned())
+    }
+
+    /// Revisions of a device's note, newest first
+    pub async fn note_history(&self, agent_id: Uuid) -> Vec<DeviceNote> {
+        self.notes.read().await.get(&agent_id)
+            .map(|notes| notes.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Save `body` as the device's note; the note is left alone when it
+    /// already says this
+    pub async fn set_note(&self, agent_id: Uuid, body: &str, author: &AuthUser) -> Result<DeviceNote, RecordError> {
+        if body.len() > MAX_NOTE_LENGTH {
+            return Err(RecordError::Invalid(format!("Notes are at most {} bytes", MAX_NOTE_LENGTH)));
+        }
+        let mut notes = self.notes.write().await;
+        let revisions = notes.entry(agent_id).or_default();
+        if let Some(current) = revisions.last().filter(|current| current.body == body) {
+            return Ok(current.clone());
+        }
+
+        let note = DeviceNote {
+            id: Uuid::new_v4(),
+            agent_id,
+            body: body.to_string(),
+            author_id: Some(author.user_id),
+            author_email: Some(author.email.clone()),
+            created_at: Utc::now(),
+        };
+        revisions.push(note.clone());
+        let pruned = revisions.len() > MAX_NOTE_REVISIONS;
+        if pruned {
+            revisions.drain(..revisions.len() - MAX_NOTE_REVISIONS);
+        }
+        drop(notes);
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.insert_device_note(&note).await {
+                warn!("Failed to store note of device {}: {}", agent_id, e);
+            } else if pruned {
+                if let Err(e) = db.prune_device_notes(agent_id, MAX_NOTE_REVISIONS as i64).await {
+                    warn!("Failed to prune notes of device {}: {}", agent_id, e);
+                }
+            }
+        }
+        Ok(note)
+    }
+
+    /// Fields defined in `tenant`, by label
+    pub async fn list_fields(&self, tenant: Tenant) -> Vec<CustomField> {
+        let mut fields: Vec<_> = self.fields.read().await.values()
+            .filter(|field| tenant.includes(field.organization_id))
+            .cloned()
+            .collect();
+        fields.sort_by_key(|field| field.label.to_lowercase());
+        fields
+    }
+
+    /// Define a field for the devices of `organization_id`
+    pub async fn create_field(
+        &self,
+        organization_id: Option<Uuid>,
+        definition: FieldDefinition,
+    ) -> Result<CustomField, RecordError> {
+        let definition = definition.validate()?;
+        let mut fields = self.fields.write().await;
+        let organization_fields = || fields.values().filter(|field| field.organization_id == organization_id);
+        if organization_fields().any(|field| field.key == definition.key) {
+            return Err(RecordError::DuplicateKey);
+        }
+        if organization_fields().count() >= custom_fields::MAX_FIELDS {
+            return Err(RecordError::Invalid(format!(
+                "An organization can define at most {} fields", custom_fields::MAX_FIELDS
+            )));
+        }
+
+        let now = Utc::now();
+        let field = CustomField {
+            id: Uuid::new_v4(),
+            organization_id,
+            key: definition.key,
+            label: definition.label,
+            field_type: definition.field_type,
+            options: DbJson(definition.options),
+            created_at: now,
+            updated_at: now,
+        };
+        fields.insert(field.id, field.clone());
+        drop(fields);
+        self.persist_field(&field).await;
+        Ok(field)
+    }
+
+    /// Relabel a field or change the options of an enum field; values that
+    /// were set before keep them until they are set again
+    pub async fn update_field(&self, tenant: Tenant, field_id: Uuid, update: FieldUpdate) -> Result<CustomField, RecordError> {
+        let mut fields = self.fields.write().await;
+        let field = fields.get_mut(&field_id)
+            .filter(|field| tenant.includes(field.organization_id))
+            .ok_or(RecordError::NotFound)?;
+        let label = update.label.as_deref().map(custom_fields::normalize_label).transpose()?;
+        let options = update.options.as_deref()
+            .map(|options| custom_fields::normalize_options(field.field_type, options))
+            .transpose()?;
+
+        if let Some(label) = label {
+            field.label = label;
+        }
+        if let Some(options) = options {
+            field.options = DbJson(options);
+        }
+        field.updated_at = Utc::now();
+        let field = field.clone();
+        drop(fields);
+        self.persist_field(&field).await;
+        Ok(field)
+    }
+
+    /// Delete a field and its values; returns how many devices had one
+    pub async fn delete_field(&self, tenant: Tenant, field_id: Uuid) -> Result<usize, RecordError> {
+        let mut fields = self.fields.write().await;
+        if !fields.get(&field_id).is_some_and(|field| tenant.includes(field.organization_id)) {
+            return Err(RecordError::NotFound);
+        }
+        fields.remove(&field_id);
+        drop(fields);
+
+        let mut cleared = 0;
+        for values in self.values.write().await.values_mut() {
+            cleared += usize::from(values.remove(&field_id).is_some());
+        }
+        // Values go with the field
+        if let Some(db) = &self.db {
+            if let Err(e) = db.delete_custom_field(field_id).await {
+                warn!("Failed to delete custom field {}: {}", field_id, e);
+            }
+        }
+        Ok(cleared)
+    }
+
+    async fn persist_field(&self, field: &CustomField) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.upsert_custom_field(field).await {
+                warn!("Failed to store custom field {}: {}", field.id, e);
+            }
+        }
+    }
+
+    /// Fields of `agent`'s organization with their values on it, by label
+    pub async fn device_fields(&self, agent: &Agent) -> Vec<DeviceField> {
+        let fields = self.list_fields(Tenant::Organization(agent.organization_id)).await;
+        let values = self.values.read().await;
+        let device_values = values.get(&agent.id);
+        fields.into_iter()
+            .map(|field| {
+                let value = device_values.and_then(|values| values.get(&field.id));
+                DeviceField {
+                    value: value.map(|value| value.value.0.clone()),
+                    updated_by: value.and_then(|value| value.updated_by),
+                    updated_at: value.map(|value| value.updated_at),
+                    field,
+                }
+            })
+            .collect()
+    }
+
+    /// Set fields of `agent` by key, clearing those set to null. Nothing is
+    /// changed unless every value fits its field.
+    pub async fn set_field_values(
+        &self,
+        agent: &Agent,
+        values: &HashMap<String, serde_json::Value>,
+        user_id: Uuid,
+    ) -> Result<Vec<DeviceField>, RecordError> {
+        let fields = self.list_fields(Tenant::Organization(agent.organization_id)).await;
+        let mut changes = Vec::with_capacity(values.len());
+        for (key, value) in values {
+            let field = fields.iter()
+                .find(|field| field.key == *key)
+                .ok_or_else(|| RecordError::Invalid(format!("No custom field '{}'", key)))?;
+            let value = if value.is_null() { None } else { Some(field.validate_value(value)?) };
+            changes.push((field.id, value));
+        }
+
+        let now = Utc::now();
+        let mut all_values = self.values.write().await;
+        let device_values = all_values.entry(agent.id).or_default();
+        let mut stored = Vec::new();
+        let mut cleared = Vec::new();
+        for (field_id, value) in changes {
+            match value {
+                Some(value) => {
+                    let value = FieldValue {
+                        agent_id: agent.id,
+                        field_id,
+                        value: DbJson(value),
+                        updated_by: Some(user_id),
+                        updated_at: now,
+                    };
+                    device_values.insert(field_id, value.clone());
+                    stored.push(value);
+                }
+                None => {
+                    if device_values.remove(&field_id).is_some() {
+                        cleared.push(field_id);
+                    }
+                }
+            }
+        }
+        drop(all_values);
+
+        if let Some(db) = &self.db {
+            for value in &stored {
+                if let Err(e) = db.upsert_device_field_value(value).await {
+                    warn!("Failed to store field {} of device {}: {}", value.field_id, agent.id, e);
+                }
+            }
+            for field_id in cleared {
+                if let Err(e) = db.delete_device_field_value(agent.id, field_id).await {
+                    warn!("Failed to clear field {} of device {}: {}", field_id, agent.id, e);
+                }
+            }
+        }
+        Ok(self.device_fields(agent).await)
+    }
+
+    /// Latest inventory of a device
+    pub async fn inventory(&self, agent_id: Uuid) -> Option<DeviceInventory> {
+        self.inventory.read().await.get(&agent_id).cloned()
+    }
+
+    /// Inventory changes of a device, newest first
+    pub async fn inventory_changes(&self, agent_id: Uuid) -> Vec<InventoryChange> {
+        self.inventory_changes.read().await.get(&agent_id)
+            .map(|changes| changes.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Keep a device's new inventory report, returning what changed since
+    /// the previous one; the first report changes nothing
+    pub async fn record_inventory(&self, agent_id: Uuid, report: Inventory, now: DateTime<Utc>) -> Vec<InventoryChange> {
+        let report = report.sanitized();
+        let mut all_inventory = self.inventory.write().await;
+        let changes: Vec<_> = all_inventory.get(&agent_id)
+            .map(|previous| inventory::diff(&previous.inventory, &report))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(field, previous, current)| InventoryChange {
+                id: Uuid::new_v4(),
+                agent_id,
+                field,
+                previous: DbJson(previous),
+                current: DbJson(current),
+                changed_at: now,
+            })
+            .collect();
+        let device_inventory = DeviceInventory {
+            agent_id,
+            inventory: DbJson(report),
+            collected_at: now,
+            updated_at: now,
+        };
+        all_inventory.insert(agent_id, device_inventory.clone());
+        drop(all_inventory);
+
+        let mut pruned = false;
+        if !changes.is_empty() {
+            let mut all_changes = self.inventory_changes.write().await;
+            let device_changes = all_changes.entry(agent_id).or_default();
+            device_changes.extend(changes.iter().cloned());
+            while device_changes.len() > MAX_INVENTORY_CHANGES {
+                device_changes.pop_front();
+                pruned = true;
+            }
+        }
+
+        if let Some(db) = &self.db {
+            if let Err(e) = db.upsert_device_inventory(&device_inventory).await {
+                warn!("Failed to store inventory of device {}: {}", agent_id, e);
+            }
+            for change in &changes {
+                if let Err(e) = db.insert_inventory_change(change).await {
+                    warn!("Failed to store inventory change of device {}: {}", agent_id, e);
+                }
+            }
+            if pruned {
+                if let Err(e) = db.prune_inventory_changes(agent_id, MAX_INVENTORY_CHANGES as i64).await {
+                    warn!("Failed to prune inventory changes of device {}: {}", agent_id, e);
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// The device, if `user` may see it, or may change it with `needs_control`
+async fn permitted_device(app_state: &AppState, user: &AuthUser, agent_id: &str, needs_control: bool) -> Result<Agent, Response> {
+    let agent_id = organizations::parse_id(agent_id, "agent").map_err(IntoResponse::into_response)?;
+    authz::check_agent_permission(app_state, user, agent_id, needs_control).await
+        .map_err(IntoResponse::into_response)?;
+    app_state.device_manager.get_agent(agent_id).await
+        .map(|(agent, _)| agent)
+        .ok_or_else(|| organizations::device_not_found(agent_id).into_response())
+}
+
+/// Current note of a device and its earlier revisions
+pub async fn api_get_device_notes(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let agent = match permitted_device(&app_state, &user, &agent_id, false).await {
+        Ok(agent) => agent,
+        Err(response) => return response,
+    };
+    let records = &app_state.device_manager.device_records;
+    let history = records.note_history(agent.id).await;
+    Json(serde_json::json!({ "note": history.first(), "history": history })).into_response()
+}
+
+/// Save a device's note as a new revision
+pub async fn api_set_device_notes(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+    Json(request): Json<NoteRequest>,
+) -> Response {
+    let agent = match permitted_device(&app_state, &user, &agent_id, true).await {
+        Ok(agent) => agent,
+        Err(response) => return response,
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.device_records.set_note(agent.id, &request.body, &user).await {
+        Ok(note) => {
+            device_manager.record_audit(
+                AuditLog::new("device", "note_updated")
+                    .actor(user.user_id.to_string())
+                    .agent(agent.id)
+                    .details(serde_json::json!({ "note_id": note.id, "length": note.body.len() })),
+            ).await;
+            Json(serde_json::json!({ "note": note })).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Custom fields of the caller's organization
+pub async fn api_list_custom_fields(State(app_state): State<AppState>, tenant: Tenant) -> Response {
+    let fields = app_state.device_manager.device_records.list_fields(tenant).await;
+    Json(serde_json::json!({ "fields": fields })).into_response()
+}
+
+pub async fn api_create_custom_field(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Json(definition): Json<FieldDefinition>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    match device_manager.device_records.create_field(tenant.organization_id(), definition).await {
+        Ok(field) => {
+            device_manager.record_audit(
+                AuditLog::new("device", "custom_field_created")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "field_id": field.id, "key": field.key, "field_type": field.field_type })),
+            ).await;
+            (StatusCode::CREATED, Json(field)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn api_update_custom_field(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(field_id): Path<String>,
+    Json(update): Json<FieldUpdate>,
+) -> Response {
+    let field_id = match organizations::parse_id(&field_id, "field") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.device_records.update_field(tenant, field_id, update).await {
+        Ok(field) => {
+            device_manager.record_audit(
+                AuditLog::new("device", "custom_field_updated")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "field_id": field.id, "key": field.key })),
+            ).await;
+            Json(field).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Delete a field along with its value on every device
+pub async fn api_delete_custom_field(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(field_id): Path<String>,
+) -> Response {
+    let field_id = match organizations::parse_id(&field_id, "field") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.device_records.delete_field(tenant, field_id).await {
+        Ok(cleared) => {
+            device_manager.record_audit(
+                AuditLog::new("device", "custom_field_deleted")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "field_id": field_id, "cleared_devices": cleared })),
+            ).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Custom fields of a device's organization with the device's values
+pub async fn api_get_device_fields(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let agent = match permitted_device(&app_state, &user, &agent_id, false).await {
+        Ok(agent) => agent,
+        Err(response) => return response,
+    };
+    let fields = app_state.device_manager.device_records.device_fields(&agent).await;
+    Json(serde_json::json!({ "fields": fields })).into_response()
+}
+
+/// Set or clear custom field values of a device
+pub async fn api_set_device_fields(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+    Json(request): Json<FieldValuesRequest>,
+) -> Response {
+    let agent = match permitted_device(&app_state, &user, &agent_id, true).await {
+        Ok(agent) => agent,
+        Err(response) => return response,
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.device_records.set_field_values(&agent, &request.values, user.user_id).await {
+        Ok(fields) => {
+            let mut keys: Vec<_> = request.values.keys().collect();
+            keys.sort();
+            device_manager.record_audit(
+                AuditLog::new("device", "custom_fields_set")
+                    .actor(user.user_id.to_string())
+                    .agent(agent.id)
+                    .details(serde_json::json!({ "fields": keys })),
+            ).await;
+            Json(serde_json::json!({ "fields": fields })).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Latest inventory of a device and what changed in it, newest first
+pub async fn api_get_device_inventory(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let agent = match permitted_device(&app_state, &user, &agent_id, false).await {
+        Ok(agent) => agent,
+        Err(response) => return response,
+    };
+    let records = &app_state.device_manager.device_records;
+    Json(serde_json::json!({
+        "inventory": records.inventory(agent.id).await,
+        "changes": records.inventory_changes(agent.id).await,
+    })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use custom_fields::FieldType;
+
+    fn agent(organization_id: Option<Uuid>) -> Agent {
+        Agent { organization_id, ..crate::database::fixtures::agent("desk-1") }
+    }
+
+    fn definition(key: &str, field_type: FieldType, options: &[&str]) -> FieldDefinition {
+        FieldDefinition {
+            key: key.to_string(),
+            label: key.to_string(),
+            field_type,
+            options: options.iter().map(|option| option.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_field_values_are_all_or_nothing() {
+        let records = DeviceRecords::new();
+        let organization = Some(Uuid::new_v4());
+        let device = agent(organization);
+        records.create_field(organization, definition("site", FieldType::Enum, &["Berlin", "Paris"])).await.unwrap();
+        records.create_field(organization, definition("purchased", FieldType::Date, &[])).await.unwrap();
+        assert_eq!(
+            records.create_field(organization, definition("Site", FieldType::Text, &[])).await.unwrap_err(),
+            RecordError::DuplicateKey
+        );
+
+        let values = HashMap::from([
+            ("site".to_string(), serde_json::json!("berlin")),
+            ("purchased".to_string(), serde_json::json!("last week")),
+        ]);
+        assert!(records.set_field_values(&device, &values, Uuid::new_v4()).await.is_err());
+        assert!(records.device_fields(&device).await.iter().all(|field| field.value.is_none()));
+
+        let values = HashMap::from([("site".to_string(), serde_json::json!("berlin"))]);
+        let fields = records.set_field_values(&device, &values, Uuid::new_v4()).await.unwrap();
+        let site = fields.iter().find(|field| field.field.key == "site").unwrap();
+        assert_eq!(site.value, Some(serde_json::json!("Berlin")));
+
+        // Another organization neither sees nor sets these fields
+        let stranger = agent(Some(Uuid::new_v4()));
+        assert!(records.device_fields(&stranger).await.is_empty());
+        assert!(records.set_field_values(&stranger, &values, Uuid::new_v4()).await.is_err());
+
+        let cleared = HashMap::from([("site".to_string(), serde_json::Value::Null)]);
+        let fields = records.set_field_values(&device, &cleared, Uuid::new_v4()).await.unwrap();
+        assert!(fields.iter().all(|field| field.value.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_inventory_changes_from_second_report() {
+        let records = DeviceRecords::new();
+        let agent_id = Uuid::new_v4();
+        let report = Inventory { os_build: Some("22631".to_string()), ..Default::default() };
+        assert!(records.record_inventory(agent_id, report.clone(), Utc::now()).await.is_empty());
+        assert!(records.record_inventory(agent_id, report, Utc::now()).await.is_empty());
+
+        let upgraded = Inventory { os_build: Some("26100".to_string()), ..Default::default() };
+        let changes = records.record_inventory(agent_id, upgraded, Utc::now()).await;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "os_build");
+        assert_eq!(records.inventory_changes(agent_id).await, changes);
+        let latest = records.inventory(agent_id).await.unwrap();
+        assert_eq!(latest.inventory.os_build.as_deref(), Some("26100"));
+    }
+}
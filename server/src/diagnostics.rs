@@ -0,0 +1,235 @@
+//! Agent diagnostics bundles
+//!
+//! `ghostlink-client logs upload` zips the agent's recent log files (and its
+//! latest crash report, if asked) and posts the archive to
+//! `POST /api/devices/:id/diagnostics`. Agents have no user token, so the
+//! upload is signed with the device key from enrollment over the agent ID, a
+//! timestamp and the SHA-256 of the bundle. Technicians list and download
+//! the bundles a device sent; the oldest are dropped once a device has more
+//! than [`KEEP_PER_DEVICE`].
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::PathBuf;
+use tokio::fs;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::api::in_tenant;
+use crate::auth::enrollment::UploadKind;
+use crate::auth::jwt::error;
+use crate::models::AuditLog;
+use crate::organizations::{self, Tenant};
+use crate::AppState;
+
+/// Largest bundle accepted for upload
+pub const MAX_BUNDLE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Bundles kept for each device
+pub const KEEP_PER_DEVICE: usize = 10;
+
+/// Local file header every zip archive starts with
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// A stored diagnostics bundle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiagnosticBundle {
+    pub name: String,
+    pub size: u64,
+    pub uploaded_at: DateTime<Utc>,
+}
+
+/// Keeps uploaded bundles on disk, one directory per device
+pub struct DiagnosticsStore {
+    storage_path: PathBuf,
+    keep: usize,
+}
+
+impl DiagnosticsStore {
+    pub fn new(storage_path: PathBuf) -> Self {
+        Self { storage_path, keep: KEEP_PER_DEVICE }
+    }
+
+    fn device_dir(&self, agent_id: Uuid) -> PathBuf {
+        self.storage_path.join(agent_id.to_string())
+    }
+
+    /// Store a bundle received at `now`, dropping the device's oldest
+    /// bundles beyond the retention count
+    pub async fn save(&self, agent_id: Uuid, bundle: &[u8], now: DateTime<Utc>) -> Result<DiagnosticBundle, String> {
+        if !bundle.starts_with(ZIP_MAGIC) {
+            return Err("Diagnostics bundle must be a zip archive".to_string());
+        }
+
+        let dir = self.device_dir(agent_id);
+        fs::create_dir_all(&dir).await
+            .map_err(|e| format!("Failed to create diagnostics directory: {}", e))?;
+        let name = format!("{}.zip", now.format("%Y%m%dT%H%M%S%3fZ"));
+        fs::write(dir.join(&name), bundle).await
+            .map_err(|e| format!("Failed to store diagnostics bundle: {}", e))?;
+
+        for stale in self.list(agent_id).await.iter().skip(self.keep) {
+            if let Err(e) = fs::remove_file(dir.join(&stale.name)).await {
+                warn!("Failed to drop diagnostics bundle {} of {}: {}", stale.name, agent_id, e);
+            }
+        }
+
+        Ok(DiagnosticBundle { name, size: bundle.len() as u64, uploaded_at: now })
+    }
+
+    /// A device's bundles, newest first
+    pub async fn list(&self, agent_id: Uuid) -> Vec<DiagnosticBundle> {
+        let mut bundles = Vec::new();
+        let Ok(mut entries) = fs::read_dir(self.device_dir(agent_id)).await else {
+            return bundles;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(uploaded_at) = uploaded_at(&name) else { continue };
+            let size = entry.metadata().await.map(|metadata| metadata.len()).unwrap_or(0);
+            bundles.push(DiagnosticBundle { name, size, uploaded_at });
+        }
+        bundles.sort_by(|a, b| b.name.cmp(&a.name));
+        bundles
+    }
+
+    /// Contents of a stored bundle
+    pub async fn read(&self, agent_id: Uuid, name: &str) -> Result<Vec<u8>, String> {
+        if uploaded_at(name).is_none() {
+            return Err(format!("No diagnostics bundle named {}", name));
+        }
+        fs::read(self.device_dir(agent_id).join(name)).await
+            .map_err(|_| format!("No diagnostics bundle named {}", name))
+    }
+}
+
+/// Upload time encoded in a bundle's file name; `None` for anything that is
+/// not a bundle name, which also keeps paths out of downloads
+fn uploaded_at(name: &str) -> Option<DateTime<Utc>> {
+    let stem = name.strip_suffix(".zip")?;
+    if !stem.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    chrono::NaiveDateTime::parse_from_str(stem, "%Y%m%dT%H%M%S%3fZ")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Accept a diagnostics bundle signed by the device it belongs to
+pub async fn api_upload_diagnostics(
+    State(app_state): State<AppState>,
+    Path(agent_id): Path<String>,
+    headers: HeaderMap,
+    bundle: Bytes,
+) -> Response {
+    let Ok(agent_uuid) = Uuid::parse_str(&agent_id) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid agent ID format");
+    };
+    let sha256 = match app_state.enrollment.verify_signed_upload(&headers, agent_uuid, &bundle, UploadKind::Diagnostics).await {
+        Ok(sha256) => sha256,
+        Err(response) => return response,
+    };
+
+    let device_manager = &app_state.device_manager;
+    match device_manager.diagnostics.save(agent_uuid, &bundle, Utc::now()).await {
+        Ok(stored) => {
+            info!("Stored diagnostics bundle {} ({} bytes) from {}", stored.name, stored.size, agent_uuid);
+            device_manager.record_audit(
+                AuditLog::new("device", "diagnostics_uploaded")
+                    .actor(agent_uuid.to_string())
+                    .agent(agent_uuid)
+                    .details(serde_json::json!({
+                        "bundle": stored.name,
+                        "size": stored.size,
+                        "sha256": sha256,
+                    })),
+            ).await;
+            (StatusCode::CREATED, Json(stored)).into_response()
+        }
+        Err(e) if e.contains("zip") => error(StatusCode::BAD_REQUEST, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Bundles a device uploaded, newest first
+pub async fn api_list_diagnostics(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let Ok(agent_uuid) = Uuid::parse_str(&agent_id) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid agent ID format");
+    };
+    if !in_tenant(&app_state, tenant, agent_uuid).await {
+        return organizations::device_not_found(agent_uuid).into_response();
+    }
+    let bundles = app_state.device_manager.diagnostics.list(agent_uuid).await;
+    Json(serde_json::json!({ "bundles": bundles })).into_response()
+}
+
+/// Download one of a device's bundles
+pub async fn api_download_diagnostics(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path((agent_id, name)): Path<(String, String)>,
+) -> Response {
+    let Ok(agent_uuid) = Uuid::parse_str(&agent_id) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid agent ID format");
+    };
+    if !in_tenant(&app_state, tenant, agent_uuid).await {
+        return organizations::device_not_found(agent_uuid).into_response();
+    }
+    match app_state.device_manager.diagnostics.read(agent_uuid, &name).await {
+        Ok(data) => (
+            [
+                (header::CONTENT_TYPE, "application/zip".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}-{}\"", agent_uuid, name)),
+            ],
+            data,
+        ).into_response(),
+        Err(e) => error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn store() -> DiagnosticsStore {
+        DiagnosticsStore::new(std::env::temp_dir().join(format!("ghostlink-diagnostics-{}", Uuid::new_v4())))
+    }
+
+    fn bundle(contents: &[u8]) -> Vec<u8> {
+        [ZIP_MAGIC, contents].concat()
+    }
+
+    #[tokio::test]
+    async fn test_bundles_are_kept_per_device_newest_first() {
+        let mut diagnostics = store();
+        diagnostics.keep = 2;
+        let (agent, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Utc::now();
+        for (offset, contents) in [b"first", b"secnd", b"third"].iter().enumerate() {
+            diagnostics.save(agent, &bundle(*contents), start + Duration::seconds(offset as i64)).await.unwrap();
+        }
+        diagnostics.save(other, &bundle(b"other"), start).await.unwrap();
+
+        let bundles = diagnostics.list(agent).await;
+        assert_eq!(bundles.len(), 2);
+        assert!(bundles[0].uploaded_at > bundles[1].uploaded_at);
+        assert_eq!(diagnostics.read(agent, &bundles[0].name).await.unwrap(), bundle(b"third"));
+        assert_eq!(diagnostics.list(other).await.len(), 1);
+
+        assert!(diagnostics.save(agent, b"not a zip", start).await.unwrap_err().contains("zip"));
+        assert!(diagnostics.read(agent, "../../etc/passwd").await.is_err());
+        let _ = std::fs::remove_dir_all(&diagnostics.storage_path);
+    }
+}
@@ -6,7 +6,7 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::sync::{RwLock, mpsc};
 use uuid::Uuid;
@@ -228,9 +228,9 @@ impl DirectConnectManager {
         
         // Add direct IP endpoints if possible
         if !matches!(client_b.nat_type, NatType::Symmetric) {
-            direct_endpoints.push(format!("{}:{}", client_b.external_ip, client_b.port));
+            direct_endpoints.push(SocketAddr::new(client_b.external_ip, client_b.port).to_string());
             if client_b.local_ip != client_b.external_ip {
-                direct_endpoints.push(format!("{}:{}", client_b.local_ip, client_b.port));
+                direct_endpoints.push(SocketAddr::new(client_b.local_ip, client_b.port).to_string());
             }
         }
         
@@ -0,0 +1,239 @@
+//! Remote file browsing for the technician's file panel
+//!
+//! Viewers send `ListDirectory`, `StatEntry`, `CreateDirectory`, `Rename` and
+//! `Delete` over the session WebSocket and get an `FsResult` back under their
+//! own `request_id`; the web UI can also list directories through
+//! `GET /api/sessions/:id/fs`. The agent confines every request to the roots
+//! its policy allows and reports failures as typed errors (`kind` plus the
+//! path). Renames and deletions land on the session timeline with their
+//! outcome, whichever way they were requested.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum::extract::ws::Message;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::api::authorized_session;
+use crate::auth::authz::{check_role, user_role, RouteClass};
+use crate::auth::jwt::AuthUser;
+use crate::device_manager::DeviceManager;
+use crate::session_events::{SessionEvent, SessionEventKind};
+use crate::AppState;
+
+/// Viewer commands handled here, all under the `File` scope
+pub const FS_COMMANDS: &[&str] = &["ListDirectory", "StatEntry", "CreateDirectory", "Rename", "Delete"];
+
+pub fn is_fs_command(cmd_type: &str) -> bool {
+    FS_COMMANDS.contains(&cmd_type)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    /// Directory to list; empty lists the roots the session may browse
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// Send a request to the session's agent, recording renames and deletions
+/// on the session timeline once the agent has answered
+pub async fn execute(
+    device_manager: &DeviceManager,
+    session_id: Uuid,
+    actor: &str,
+    command: serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let result = device_manager.send_fs_command(session_id, command.clone()).await;
+
+    if let Some(details) = change_details(&command, &result) {
+        match device_manager.get_session(session_id).await {
+            Some(session) => {
+                let event = SessionEvent::new(session_id, SessionEventKind::FileOperation, actor, details);
+                device_manager.record_session_events(&session, session.user_id, vec![event]).await;
+            }
+            None => debug!("Session {} ended before its file operation could be recorded", session_id),
+        }
+    }
+    result
+}
+
+/// Answer a viewer's request on its WebSocket under the viewer's request ID
+pub async fn relay_session_request(
+    device_manager: Arc<DeviceManager>,
+    session_id: Uuid,
+    actor: String,
+    command: serde_json::Value,
+) {
+    let request_id = command.get("request_id").cloned().unwrap_or(serde_json::Value::Null);
+    let mut reply = match execute(&device_manager, session_id, &actor, command).await {
+        Ok(result) => result,
+        Err(e) => serde_json::json!({
+            "type": "FsResult",
+            "success": false,
+            "result": null,
+            "error": { "kind": "unavailable", "reason": e },
+        }),
+    };
+    reply["session_id"] = serde_json::Value::String(session_id.to_string());
+    reply["request_id"] = request_id;
+
+    if let Err(e) = device_manager.send_to_session(session_id, Message::Text(reply.to_string())).await {
+        debug!("Failed to answer file browser request of session {}: {}", session_id, e);
+    }
+}
+
+/// Whether a request changes the device's files, and so needs control of it
+/// rather than only a view
+pub fn needs_control(command: &serde_json::Value) -> bool {
+    matches!(command.get("type").and_then(|v| v.as_str()), Some("CreateDirectory" | "Rename" | "Delete"))
+}
+
+/// Timeline details of a rename or deletion and how it went; `None` for
+/// requests that change nothing
+pub fn change_details(
+    command: &serde_json::Value,
+    result: &Result<serde_json::Value, String>,
+) -> Option<serde_json::Value> {
+    let field = |name: &str| command.get(name).cloned().unwrap_or(serde_json::Value::Null);
+    let mut details = match command.get("type").and_then(|v| v.as_str()) {
+        Some("Rename") => serde_json::json!({ "operation": "rename", "from": field("from"), "to": field("to") }),
+        Some("Delete") => serde_json::json!({
+            "operation": "delete",
+            "path": field("path"),
+            "recursive": command.get("recursive").and_then(|v| v.as_bool()).unwrap_or(false),
+        }),
+        _ => return None,
+    };
+
+    let (success, error) = match result {
+        Ok(result) => (
+            result.get("success").and_then(|v| v.as_bool()).unwrap_or(false),
+            result.get("error").cloned().unwrap_or(serde_json::Value::Null),
+        ),
+        Err(e) => (false, serde_json::json!({ "kind": "unavailable", "reason": e })),
+    };
+    details["success"] = serde_json::Value::Bool(success);
+    if !error.is_null() {
+        details["error"] = error;
+    }
+    Some(details)
+}
+
+/// HTTP status for an error `kind` reported by the agent
+pub fn error_status(kind: &str) -> StatusCode {
+    match kind {
+        "not_found" => StatusCode::NOT_FOUND,
+        "outside_roots" | "permission_denied" => StatusCode::FORBIDDEN,
+        "invalid_path" | "not_a_directory" => StatusCode::BAD_REQUEST,
+        "already_exists" | "not_empty" => StatusCode::CONFLICT,
+        "unavailable" => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+fn error_response(status: StatusCode, error: serde_json::Value) -> Response {
+    let kind = error.get("kind").and_then(|v| v.as_str()).unwrap_or("io").to_string();
+    let subject = ["path", "reason", "message"].iter()
+        .find_map(|field| error.get(*field).and_then(|v| v.as_str()))
+        .unwrap_or_default();
+    (status, Json(serde_json::json!({
+        "error": format!("{}: {}", kind.replace('_', " "), subject),
+        "kind": kind,
+        "details": error,
+    }))).into_response()
+}
+
+/// List a directory on the session's device, a page at a time
+pub async fn api_list_directory(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    if let Err(e) = user_role(&user).and_then(|role| check_role(&role, RouteClass::SessionControl)) {
+        return e.into_response();
+    }
+    let command = serde_json::json!({
+        "type": "ListDirectory",
+        "path": query.path,
+        "offset": query.offset,
+        "limit": query.limit,
+    });
+    let session_id = match authorized_session(&app_state, &user, &session_id, needs_control(&command)).await {
+        Ok(session) => session.id,
+        Err(response) => return response,
+    };
+
+    let result = match execute(&app_state.device_manager, session_id, &user.email, command).await {
+        Ok(result) => result,
+        Err(e) => {
+            return error_response(StatusCode::BAD_GATEWAY, serde_json::json!({ "kind": "unavailable", "reason": e }));
+        }
+    };
+
+    if result.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
+        let mut listing = result.get("result").cloned().unwrap_or(serde_json::Value::Null);
+        if let Some(listing) = listing.as_object_mut() {
+            listing.remove("kind");
+        }
+        Json(listing).into_response()
+    } else {
+        let error = result.get("error").cloned().unwrap_or(serde_json::Value::Null);
+        let kind = error.get("kind").and_then(|v| v.as_str()).unwrap_or("");
+        error_response(error_status(kind), error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(success: bool, error: serde_json::Value) -> Result<serde_json::Value, String> {
+        Ok(serde_json::json!({ "type": "FsResult", "success": success, "result": null, "error": error }))
+    }
+
+    #[test]
+    fn test_changes_are_described_with_their_outcome() {
+        let rename = serde_json::json!({ "type": "Rename", "from": "/home/a/x", "to": "/home/a/y" });
+        let details = change_details(&rename, &answer(true, serde_json::Value::Null)).unwrap();
+        assert_eq!(details, serde_json::json!({
+            "operation": "rename", "from": "/home/a/x", "to": "/home/a/y", "success": true,
+        }));
+
+        let delete = serde_json::json!({ "type": "Delete", "path": "/etc/passwd" });
+        let refused = serde_json::json!({ "kind": "outside_roots", "path": "/etc/passwd" });
+        let details = change_details(&delete, &answer(false, refused.clone())).unwrap();
+        assert_eq!(details["recursive"], false);
+        assert_eq!(details["success"], false);
+        assert_eq!(details["error"], refused);
+
+        // An agent that never answered may still have deleted it
+        let details = change_details(&delete, &Err("Agent did not answer".to_string())).unwrap();
+        assert_eq!(details["error"]["kind"], "unavailable");
+
+        let list = serde_json::json!({ "type": "ListDirectory", "path": "/home/a" });
+        assert!(change_details(&list, &answer(true, serde_json::Value::Null)).is_none());
+        assert!(needs_control(&rename) && needs_control(&delete));
+        assert!(!needs_control(&list));
+    }
+
+    #[test]
+    fn test_error_status() {
+        assert_eq!(error_status("outside_roots"), StatusCode::FORBIDDEN);
+        assert_eq!(error_status("permission_denied"), StatusCode::FORBIDDEN);
+        assert_eq!(error_status("not_found"), StatusCode::NOT_FOUND);
+        assert_eq!(error_status("not_a_directory"), StatusCode::BAD_REQUEST);
+        assert_eq!(error_status("io"), StatusCode::BAD_GATEWAY);
+        assert!(FS_COMMANDS.iter().all(|command| is_fs_command(command)));
+        assert!(!is_fs_command("FileTransferStart"));
+    }
+}
@@ -0,0 +1,713 @@
+//! Files dropped or pasted onto the session viewer
+//!
+//! The viewer posts the files to `POST /api/sessions/:id/files` as a
+//! multipart form. The relay checks the device's policy, then spools each
+//! file to disk while counting and hashing it, refusing the whole upload as
+//! soon as one passes the size limit, so nothing oversized ever reaches the
+//! agent. Once everything is spooled it answers `202 Accepted` and uploads
+//! the files side by side as ordinary chunked transfers marked `drop`,
+//! which the agent saves to its drop directory. Their combined progress
+//! goes to the viewer as `FileDropProgress` messages.
+//!
+//! Viewers may also stream a drop over the session socket themselves
+//! (`FileTransferStart` with `drop` set); those starts are held to the same
+//! policy and limit before they are relayed.
+
+use axum::{
+    body::Bytes,
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum::extract::ws::Message;
+use futures_util::{Stream, StreamExt};
+use ring::digest::{Context, SHA256};
+use serde::Serialize;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::api::authorized_session;
+use crate::auth::jwt::{error, AuthUser};
+use crate::device_manager::DeviceManager;
+use crate::policies::PolicyDocument;
+use crate::relay::protocol::{self, Envelope, FileChunkBody, MessageKind};
+use crate::AppState;
+
+/// Chunk size of the transfers a drop is relayed as
+pub const DROP_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Largest file a drop may carry, whatever the policy allows
+pub const MAX_DROP_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Most files one drop may carry
+pub const MAX_DROP_FILES: usize = 20;
+
+/// Body limit of the upload route
+pub const MAX_DROP_REQUEST_SIZE: usize = 4 * 1024 * 1024 * 1024;
+
+/// Files of one drop uploaded to the agent at the same time
+const DROP_CONCURRENCY: usize = 4;
+
+/// How long the agent has to accept a file, and to confirm it once all
+/// chunks are sent; the latter includes checksumming up to a gigabyte
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+const COMPLETE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often the viewer hears how the drop is going
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DropError {
+    /// The device's policy does not take drops
+    Refused(String),
+    TooLarge { filename: String, limit: u64 },
+    TooManyFiles,
+    NoFiles,
+    Malformed(String),
+    Io(String),
+}
+
+impl std::fmt::Display for DropError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DropError::Refused(reason) => write!(f, "{}", reason),
+            DropError::TooLarge { filename, limit } => {
+                write!(f, "{} exceeds the {} byte limit for dropped files", filename, limit)
+            }
+            DropError::TooManyFiles => write!(f, "A drop may carry at most {} files", MAX_DROP_FILES),
+            DropError::NoFiles => write!(f, "The upload carries no files"),
+            DropError::Malformed(message) => write!(f, "Malformed upload: {}", message),
+            DropError::Io(message) => write!(f, "Failed to spool upload: {}", message),
+        }
+    }
+}
+
+impl IntoResponse for DropError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            DropError::Refused(_) => StatusCode::FORBIDDEN,
+            DropError::TooLarge { .. } | DropError::TooManyFiles => StatusCode::PAYLOAD_TOO_LARGE,
+            DropError::NoFiles | DropError::Malformed(_) => StatusCode::BAD_REQUEST,
+            DropError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        error(status, self.to_string())
+    }
+}
+
+/// Largest file `policy` lets a drop carry, or why it takes none
+pub fn drop_limit(policy: &PolicyDocument) -> Result<u64, DropError> {
+    if policy.file_transfer_enabled == Some(false) {
+        return Err(DropError::Refused("File transfer is disabled by policy".to_string()));
+    }
+    if policy.file_drop_enabled == Some(false) {
+        return Err(DropError::Refused("File drops are disabled by policy".to_string()));
+    }
+    Ok(policy.max_file_drop_bytes.map_or(MAX_DROP_FILE_SIZE, |bytes| bytes.min(MAX_DROP_FILE_SIZE)))
+}
+
+async fn session_drop_limit(device_manager: &DeviceManager, agent_id: Uuid) -> Result<u64, DropError> {
+    let policy = device_manager.effective_policy(agent_id).await.map(|(policy, _)| policy).unwrap_or_default();
+    drop_limit(&policy)
+}
+
+/// Check a drop a viewer streams over its socket before relaying its start;
+/// other transfers pass
+pub async fn admit_socket_transfer(
+    device_manager: &DeviceManager,
+    session_id: Uuid,
+    start: &serde_json::Value,
+) -> Result<(), DropError> {
+    if start.get("drop").and_then(|v| v.as_bool()) != Some(true) {
+        return Ok(());
+    }
+    let session = device_manager.get_active_session(session_id).await.map_err(DropError::Refused)?;
+    let limit = session_drop_limit(device_manager, session.agent_id).await?;
+    let size = start.get("total_size").and_then(|v| v.as_u64()).unwrap_or(u64::MAX);
+    if size > limit {
+        let filename = start.get("filename").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        return Err(DropError::TooLarge { filename, limit });
+    }
+    Ok(())
+}
+
+/// A dropped file waiting on disk to be relayed; the spool goes when this does
+#[derive(Debug)]
+pub struct SpooledFile {
+    pub filename: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256, which the agent checks the saved file against
+    pub sha256: String,
+    path: PathBuf,
+}
+
+impl SpooledFile {
+    pub fn total_chunks(&self) -> u32 {
+        self.size.div_ceil(DROP_CHUNK_SIZE) as u32
+    }
+
+    /// Length of chunk `index`, matching how the agent splits the file
+    pub fn chunk_len(&self, index: u32) -> u64 {
+        self.size.saturating_sub(index as u64 * DROP_CHUNK_SIZE).min(DROP_CHUNK_SIZE)
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove spooled drop {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// The plain name of an uploaded file; browsers send no directories, but
+/// the agent takes nothing else
+fn drop_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    (!name.is_empty() && name != "." && name != ".." && !name.contains('\0')).then(|| name.to_string())
+}
+
+/// Write `data` to a spool file, refusing it once it passes `limit` bytes
+pub async fn spool<S, E>(filename: String, data: S, limit: u64) -> Result<SpooledFile, DropError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let path = std::env::temp_dir().join(format!("ghostlink-drop-{}", Uuid::new_v4()));
+    let mut file = File::create(&path).await.map_err(|e| DropError::Io(e.to_string()))?;
+    // From here on an early return removes the spool
+    let mut spooled = SpooledFile { filename, size: 0, sha256: String::new(), path };
+    let mut hash = Context::new(&SHA256);
+
+    futures_util::pin_mut!(data);
+    while let Some(piece) = data.next().await {
+        let piece = piece.map_err(|e| DropError::Malformed(e.to_string()))?;
+        spooled.size += piece.len() as u64;
+        if spooled.size > limit {
+            return Err(DropError::TooLarge { filename: spooled.filename.clone(), limit });
+        }
+        hash.update(&piece);
+        file.write_all(&piece).await.map_err(|e| DropError::Io(e.to_string()))?;
+    }
+    file.flush().await.map_err(|e| DropError::Io(e.to_string()))?;
+
+    spooled.sha256 = hash.finish().as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(spooled)
+}
+
+/// Spool every file of a multipart upload; parts that are not files are skipped
+pub async fn read_upload(mut multipart: Multipart, limit: u64) -> Result<Vec<SpooledFile>, DropError> {
+    let mut files = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Err(DropError::Malformed(e.to_string())),
+        };
+        let Some(filename) = field.file_name().and_then(drop_filename) else {
+            debug!("Ignoring upload field {}", field.name().unwrap_or_default());
+            continue;
+        };
+        if files.len() == MAX_DROP_FILES {
+            return Err(DropError::TooManyFiles);
+        }
+        files.push(spool(filename, field, limit).await?);
+    }
+    if files.is_empty() {
+        return Err(DropError::NoFiles);
+    }
+    Ok(files)
+}
+
+/// Chunk `index` of a dropped file, as a binary envelope for agents that
+/// speak them and as JSON for the rest
+pub fn chunk_message(session_id: Uuid, transfer_id: &str, file: &SpooledFile, index: u32, data: &[u8], binary: bool) -> Message {
+    let total_chunks = file.total_chunks();
+    if !binary {
+        return Message::Text(serde_json::json!({
+            "type": "FileTransfer",
+            "session_id": session_id.to_string(),
+            "transfer_id": transfer_id,
+            "file_data": data,
+            "filename": file.filename,
+            "total_size": file.size,
+            "chunk_index": index,
+            "total_chunks": total_chunks,
+        }).to_string());
+    }
+
+    let body = FileChunkBody {
+        transfer_id,
+        filename: &file.filename,
+        total_size: file.size,
+        chunk_index: index,
+        total_chunks,
+        data,
+    };
+    let last = index.saturating_add(1) >= total_chunks;
+    Message::Binary(
+        Envelope::new(MessageKind::FileChunk, session_id, &body.encode())
+            .with_flags(if last { protocol::flags::LAST } else { 0 })
+            .encode(),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropState {
+    Queued,
+    Sending,
+    Saved,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DroppedFile {
+    pub filename: String,
+    pub size: u64,
+    pub sent: u64,
+    pub state: DropState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Combined progress of the files of one drop
+#[derive(Debug)]
+pub struct DropProgress {
+    upload_id: Uuid,
+    files: Vec<DroppedFile>,
+    /// Whether anything happened since the last update went out
+    changed: bool,
+}
+
+impl DropProgress {
+    pub fn new<'a>(upload_id: Uuid, files: impl IntoIterator<Item = (&'a str, u64)>) -> Self {
+        let files = files
+            .into_iter()
+            .map(|(filename, size)| DroppedFile {
+                filename: filename.to_string(),
+                size,
+                sent: 0,
+                state: DropState::Queued,
+                error: None,
+            })
+            .collect();
+        Self { upload_id, files, changed: true }
+    }
+
+    /// `bytes` more of file `index` went to the agent
+    pub fn advance(&mut self, index: usize, bytes: u64) {
+        if let Some(file) = self.files.get_mut(index) {
+            file.state = DropState::Sending;
+            file.sent = (file.sent + bytes).min(file.size);
+            self.changed = true;
+        }
+    }
+
+    /// The agent saved file `index`, or it failed on the way
+    pub fn finish(&mut self, index: usize, result: Result<(), String>) {
+        if let Some(file) = self.files.get_mut(index) {
+            match result {
+                Ok(()) => {
+                    file.state = DropState::Saved;
+                    file.sent = file.size;
+                }
+                Err(e) => {
+                    file.state = DropState::Failed;
+                    file.error = Some(e);
+                }
+            }
+            self.changed = true;
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.files.iter().all(|file| matches!(file.state, DropState::Saved | DropState::Failed))
+    }
+
+    /// The `FileDropProgress` message. Failed files leave the byte counts,
+    /// so the combined figure still reaches its total.
+    pub fn message(&self) -> serde_json::Value {
+        let count = |state: DropState| self.files.iter().filter(|file| file.state == state).count();
+        let remaining = || self.files.iter().filter(|file| file.state != DropState::Failed);
+        serde_json::json!({
+            "type": "FileDropProgress",
+            "upload_id": self.upload_id,
+            "files_total": self.files.len(),
+            "files_saved": count(DropState::Saved),
+            "files_failed": count(DropState::Failed),
+            "bytes_sent": remaining().map(|file| file.sent).sum::<u64>(),
+            "bytes_total": remaining().map(|file| file.size).sum::<u64>(),
+            "done": self.is_done(),
+            "files": self.files,
+        })
+    }
+
+    /// The message, if anything changed since the last one was taken
+    pub fn take_update(&mut self) -> Option<serde_json::Value> {
+        std::mem::take(&mut self.changed).then(|| self.message())
+    }
+}
+
+/// Upload the spooled files of a drop to the session's agent, reporting
+/// their combined progress to the viewer
+pub async fn relay_drop(device_manager: Arc<DeviceManager>, session_id: Uuid, upload_id: Uuid, files: Vec<SpooledFile>) {
+    let progress = Arc::new(Mutex::new(DropProgress::new(
+        upload_id,
+        files.iter().map(|file| (file.filename.as_str(), file.size)),
+    )));
+    let count = files.len();
+    let uploads = futures_util::stream::iter(files.into_iter().enumerate())
+        .map(|(index, file)| relay_file(Arc::clone(&device_manager), session_id, index, file, Arc::clone(&progress)))
+        .buffer_unordered(DROP_CONCURRENCY)
+        .collect::<Vec<()>>();
+    tokio::pin!(uploads);
+
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut uploads => break,
+            _ = ticker.tick() => report(&device_manager, session_id, &progress).await,
+        }
+    }
+    report(&device_manager, session_id, &progress).await;
+    info!("Drop {} of {} files to session {} finished", upload_id, count, session_id);
+}
+
+async fn relay_file(
+    device_manager: Arc<DeviceManager>,
+    session_id: Uuid,
+    index: usize,
+    file: SpooledFile,
+    progress: Arc<Mutex<DropProgress>>,
+) {
+    let result = upload_file(&device_manager, session_id, &file, |bytes| {
+        progress.lock().unwrap().advance(index, bytes);
+    })
+    .await;
+    if let Err(e) = &result {
+        warn!("Drop of {} to session {} failed: {}", file.filename, session_id, e);
+    }
+    progress.lock().unwrap().finish(index, result);
+}
+
+async fn report(device_manager: &DeviceManager, session_id: Uuid, progress: &Mutex<DropProgress>) {
+    let Some(update) = progress.lock().unwrap().take_update() else {
+        return;
+    };
+    if let Err(e) = device_manager.send_to_session(session_id, Message::Text(update.to_string())).await {
+        debug!("Failed to report drop progress to session {}: {}", session_id, e);
+    }
+}
+
+/// Relay one file as a transfer the relay owns, so the agent's answers come
+/// back here instead of to the viewer
+async fn upload_file(
+    device_manager: &DeviceManager,
+    session_id: Uuid,
+    file: &SpooledFile,
+    on_sent: impl Fn(u64),
+) -> Result<(), String> {
+    let transfer_id = Uuid::new_v4();
+    let mut replies = device_manager.open_drop_transfer(session_id, transfer_id).await;
+    let result = send_file(device_manager, session_id, transfer_id, file, &mut replies, on_sent).await;
+    device_manager.close_drop_transfer(transfer_id).await;
+    result
+}
+
+async fn send_file(
+    device_manager: &DeviceManager,
+    session_id: Uuid,
+    transfer_id: Uuid,
+    file: &SpooledFile,
+    replies: &mut mpsc::Receiver<serde_json::Value>,
+    on_sent: impl Fn(u64),
+) -> Result<(), String> {
+    let start = serde_json::json!({
+        "type": "FileTransferStart",
+        "session_id": session_id.to_string(),
+        "transfer_id": transfer_id.to_string(),
+        "filename": file.filename,
+        "total_size": file.size,
+        "total_chunks": file.total_chunks(),
+        "chunk_size": DROP_CHUNK_SIZE,
+        "sha256": file.sha256,
+        "drop": true,
+    });
+    device_manager.send_to_session_agent(session_id, Message::Text(start.to_string())).await?;
+
+    // The agent names the chunk to start from, or settles an empty file at once
+    let next_chunk = match next_reply(replies, START_TIMEOUT).await? {
+        Reply::Resume(next_chunk) => next_chunk.min(file.total_chunks()),
+        Reply::Complete => return Ok(()),
+    };
+    on_sent(next_chunk as u64 * DROP_CHUNK_SIZE);
+
+    let binary = device_manager.session_binary_protocol(session_id).await.is_some();
+    let mut spool = File::open(&file.path).await.map_err(|e| e.to_string())?;
+    spool.seek(SeekFrom::Start(next_chunk as u64 * DROP_CHUNK_SIZE)).await.map_err(|e| e.to_string())?;
+    let mut buffer = vec![0u8; DROP_CHUNK_SIZE as usize];
+    for index in next_chunk..file.total_chunks() {
+        // A refusal part way through, e.g. a full disk, stops the upload
+        if let Ok(reply) = replies.try_recv() {
+            return match parse_reply(&reply)? {
+                Reply::Complete => Ok(()),
+                Reply::Resume(_) => Err("Agent restarted the transfer".to_string()),
+            };
+        }
+
+        let len = file.chunk_len(index) as usize;
+        spool.read_exact(&mut buffer[..len]).await.map_err(|e| e.to_string())?;
+        let chunk = chunk_message(session_id, &transfer_id.to_string(), file, index, &buffer[..len], binary);
+        device_manager.send_to_session_agent(session_id, chunk).await?;
+        on_sent(len as u64);
+    }
+
+    match next_reply(replies, COMPLETE_TIMEOUT).await? {
+        Reply::Complete => Ok(()),
+        Reply::Resume(_) => Err("Agent restarted the transfer".to_string()),
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    Resume(u32),
+    Complete,
+}
+
+async fn next_reply(replies: &mut mpsc::Receiver<serde_json::Value>, timeout: Duration) -> Result<Reply, String> {
+    match tokio::time::timeout(timeout, replies.recv()).await {
+        Ok(Some(reply)) => parse_reply(&reply),
+        Ok(None) => Err("Transfer was dropped".to_string()),
+        Err(_) => Err(format!("Agent did not answer within {}s", timeout.as_secs())),
+    }
+}
+
+/// What an agent's answer means for the upload; failures and cancellations
+/// carry the agent's reason
+fn parse_reply(reply: &serde_json::Value) -> Result<Reply, String> {
+    let reason = |field: &str| {
+        reply.get(field).and_then(|v| v.as_str()).unwrap_or("no reason given").to_string()
+    };
+    match reply.get("type").and_then(|v| v.as_str()) {
+        Some("FileTransferResume") => {
+            let next_chunk = reply.get("next_chunk").and_then(|v| v.as_u64()).unwrap_or(0);
+            Ok(Reply::Resume(u32::try_from(next_chunk).unwrap_or(u32::MAX)))
+        }
+        Some("FileTransferComplete") if reply.get("success").and_then(|v| v.as_bool()) == Some(true) => {
+            Ok(Reply::Complete)
+        }
+        Some("FileTransferComplete") => Err(reason("error")),
+        _ => Err(format!("Agent cancelled the transfer: {}", reason("reason"))),
+    }
+}
+
+/// Take files dropped onto the viewer and relay them to the session's device
+pub async fn api_upload_files(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+    multipart: Multipart,
+) -> Response {
+    let session_id = match authorized_session(&app_state, &user, &session_id, true).await {
+        Ok(session) => session.id,
+        Err(response) => return response,
+    };
+    let device_manager = &app_state.device_manager;
+    let session = match device_manager.get_active_session(session_id).await {
+        Ok(session) => session,
+        Err(e) => return error(StatusCode::CONFLICT, e),
+    };
+
+    // Policy first, so a refused drop is not uploaded for nothing
+    let files = match session_drop_limit(device_manager, session.agent_id).await {
+        Ok(limit) => read_upload(multipart, limit).await,
+        Err(e) => Err(e),
+    };
+    let files = match files {
+        Ok(files) => files,
+        Err(e) => {
+            debug!("Refused drop from {} to session {}: {}", user.email, session_id, e);
+            return e.into_response();
+        }
+    };
+
+    let upload_id = Uuid::new_v4();
+    let listed: Vec<serde_json::Value> = files.iter()
+        .map(|file| serde_json::json!({ "filename": file.filename, "size": file.size, "sha256": file.sha256 }))
+        .collect();
+    info!("{} dropped {} files onto session {} as upload {}", user.email, files.len(), session_id, upload_id);
+    tokio::spawn(relay_drop(Arc::clone(device_manager), session_id, upload_id, files));
+
+    (StatusCode::ACCEPTED, Json(serde_json::json!({ "upload_id": upload_id, "files": listed }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::extract::FromRequest;
+    use axum::http::Request;
+
+    async fn multipart(parts: &[(&str, Option<&str>, &[u8])]) -> Multipart {
+        let mut body = Vec::new();
+        for (name, filename, data) in parts {
+            body.extend_from_slice(b"--DROP\r\n");
+            let disposition = match filename {
+                Some(filename) => format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n", name, filename),
+                None => format!("Content-Disposition: form-data; name=\"{}\"\r\n", name),
+            };
+            body.extend_from_slice(disposition.as_bytes());
+            body.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\n");
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--DROP--\r\n");
+
+        let request = Request::builder()
+            .method("POST")
+            .header("content-type", "multipart/form-data; boundary=DROP")
+            .body(Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    fn decode_chunk(message: &Message) -> (u32, u32, Vec<u8>, bool) {
+        let Message::Binary(data) = message else { panic!("expected a binary chunk") };
+        let envelope = Envelope::decode(data).unwrap();
+        let last = envelope.flags & protocol::flags::LAST != 0;
+        let chunk = FileChunkBody::decode(envelope.payload).unwrap();
+        (chunk.chunk_index, chunk.total_chunks, chunk.data.to_vec(), last)
+    }
+
+    #[tokio::test]
+    async fn test_multipart_is_spooled_and_relayed_as_chunks() {
+        let report: Vec<u8> = (0..DROP_CHUNK_SIZE as usize * 2 + 1000).map(|i| (i % 251) as u8).collect();
+        let upload = multipart(&[
+            ("note", None, b"not a file"),
+            ("files", Some("report.bin"), &report),
+            ("files", Some("C:\\Users\\tech\\empty.txt"), b""),
+        ]).await;
+        let files = read_upload(upload, MAX_DROP_FILE_SIZE).await.unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].filename, "empty.txt");
+        assert_eq!(files[1].total_chunks(), 0);
+        let file = &files[0];
+        assert_eq!((file.filename.as_str(), file.size), ("report.bin", report.len() as u64));
+        let digest = ring::digest::digest(&SHA256, &report);
+        assert_eq!(file.sha256, digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect::<String>());
+
+        // Chunks cut from the spool put the file back together
+        let session_id = Uuid::new_v4();
+        let mut spool = File::open(&file.path).await.unwrap();
+        let mut rebuilt = Vec::new();
+        for index in 0..file.total_chunks() {
+            let mut data = vec![0u8; file.chunk_len(index) as usize];
+            spool.read_exact(&mut data).await.unwrap();
+            let message = chunk_message(session_id, "t-1", file, index, &data, true);
+            let (chunk_index, total_chunks, data, last) = decode_chunk(&message);
+            assert_eq!((chunk_index, total_chunks), (index, 3));
+            assert_eq!(last, index == 2);
+            rebuilt.extend(data);
+        }
+        assert_eq!(rebuilt, report);
+
+        let json = chunk_message(session_id, "t-1", file, 2, &report[..1000], false);
+        let Message::Text(json) = json else { panic!("expected a JSON chunk") };
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["type"], "FileTransfer");
+        assert_eq!(json["file_data"].as_array().unwrap().len(), 1000);
+
+        // Dropping the file removes its spool
+        let path = file.path.clone();
+        drop(files);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_upload_limits() {
+        let data = vec![1u8; 4096];
+        let upload = multipart(&[("files", Some("ok.bin"), &data[..100]), ("files", Some("big.bin"), &data)]).await;
+        assert_eq!(
+            read_upload(upload, 1000).await.unwrap_err(),
+            DropError::TooLarge { filename: "big.bin".to_string(), limit: 1000 },
+        );
+
+        let pieces = futures_util::stream::iter((0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from_static(b"abc"))));
+        let too_large = spool("pieces.bin".to_string(), pieces, 10).await.unwrap_err();
+        assert!(matches!(too_large, DropError::TooLarge { .. }));
+
+        let many: Vec<(&str, Option<&str>, &[u8])> = (0..=MAX_DROP_FILES).map(|_| ("files", Some("a.txt"), &b"a"[..])).collect();
+        assert_eq!(read_upload(multipart(&many).await, 1000).await.unwrap_err(), DropError::TooManyFiles);
+        assert_eq!(read_upload(multipart(&[("note", None, b"x")]).await, 1000).await.unwrap_err(), DropError::NoFiles);
+        assert_eq!(read_upload(multipart(&[("files", Some(".."), b"x")]).await, 1000).await.unwrap_err(), DropError::NoFiles);
+    }
+
+    #[test]
+    fn test_policy_decides_drops() {
+        assert_eq!(drop_limit(&PolicyDocument::default()), Ok(MAX_DROP_FILE_SIZE));
+        let capped = PolicyDocument { max_file_drop_bytes: Some(5_000_000), ..Default::default() };
+        assert_eq!(drop_limit(&capped), Ok(5_000_000));
+
+        let no_drops = PolicyDocument { file_drop_enabled: Some(false), ..Default::default() };
+        assert!(matches!(drop_limit(&no_drops), Err(DropError::Refused(_))));
+        // No file transfer means no drops either
+        let no_transfers = PolicyDocument {
+            file_transfer_enabled: Some(false),
+            file_drop_enabled: Some(true),
+            ..Default::default()
+        };
+        assert!(matches!(drop_limit(&no_transfers), Err(DropError::Refused(_))));
+    }
+
+    #[test]
+    fn test_progress_aggregates_files() {
+        let mut progress = DropProgress::new(Uuid::new_v4(), [("a.bin", 1000), ("b.bin", 3000), ("c.bin", 500)]);
+        assert_eq!(progress.take_update().unwrap()["bytes_total"], 4500);
+        assert!(progress.take_update().is_none());
+
+        progress.advance(0, 600);
+        progress.advance(1, 1000);
+        let update = progress.take_update().unwrap();
+        assert_eq!(update["bytes_sent"], 1600);
+        assert_eq!(update["files"][0]["state"], "sending");
+        assert_eq!(update["done"], false);
+
+        // A failed file leaves the totals; a saved one counts in full
+        progress.finish(1, Err("Disk full".to_string()));
+        progress.advance(0, 400);
+        progress.finish(0, Ok(()));
+        let update = progress.take_update().unwrap();
+        assert_eq!((update["bytes_sent"].as_u64(), update["bytes_total"].as_u64()), (Some(1000), Some(1500)));
+        assert_eq!((update["files_saved"].as_u64(), update["files_failed"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(update["files"][1]["error"], "Disk full");
+        assert_eq!(update["done"], false);
+
+        progress.finish(2, Ok(()));
+        let update = progress.take_update().unwrap();
+        assert_eq!(update["bytes_sent"], update["bytes_total"]);
+        assert_eq!(update["done"], true);
+    }
+
+    #[test]
+    fn test_agent_replies() {
+        let resume = serde_json::json!({ "type": "FileTransferResume", "next_chunk": 3 });
+        assert!(matches!(parse_reply(&resume), Ok(Reply::Resume(3))));
+        let saved = serde_json::json!({ "type": "FileTransferComplete", "success": true });
+        assert!(matches!(parse_reply(&saved), Ok(Reply::Complete)));
+        let refused = serde_json::json!({ "type": "FileTransferComplete", "success": false, "error": "File drops are disabled" });
+        assert_eq!(parse_reply(&refused).err().as_deref(), Some("File drops are disabled"));
+        let cancelled = serde_json::json!({ "type": "FileTransferCancel", "reason": "Cancelled" });
+        assert!(parse_reply(&cancelled).unwrap_err().contains("Cancelled"));
+    }
+}
@@ -0,0 +1,139 @@
+//! Listening sockets and address notation
+//!
+//! Listeners bind `::` by default. With `dual_stack` the socket clears
+//! `IPV6_V6ONLY`, so IPv4 clients reach it too and show up as v4-mapped
+//! addresses; `IpAddr::to_canonical` turns those back into plain IPv4. Hosts
+//! without IPv6 can't create the socket at all; those fall back to
+//! `0.0.0.0` with a warning.
+
+use anyhow::{bail, Result};
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tracing::warn;
+
+/// Backlog of the HTTP listener
+const BACKLOG: i32 = 1024;
+
+/// Parse a listen host, an IP literal with or without IPv6 brackets
+pub fn parse_host(name: &str, value: &str) -> Result<IpAddr> {
+    let literal = value.trim().strip_prefix('[').and_then(|rest| rest.strip_suffix(']')).unwrap_or(value.trim());
+    match literal.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) if value.trim().starts_with('[') => {
+            bail!("{} {:?}: only IPv6 addresses go in brackets", name, value)
+        }
+        Ok(ip) => Ok(ip),
+        Err(_) if literal.contains(':') && literal.contains('.') && literal.matches(':').count() == 1 => {
+            bail!("{} {:?} is an address with a port; give the port separately", name, value)
+        }
+        Err(_) => bail!("{} {:?} is not an IP address, e.g. `::` or `0.0.0.0`", name, value),
+    }
+}
+
+/// Parse `host:port`, with IPv6 hosts in brackets as in `[::]:8443`
+pub fn parse_socket_addr(name: &str, value: &str) -> Result<SocketAddr> {
+    let value = value.trim();
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if value.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv6()) {
+        bail!("{} {:?}: IPv6 addresses need brackets and a port, e.g. `[::]:8443`", name, value);
+    }
+    bail!("{} {:?} is not an address and port, e.g. `[::]:8443` or `0.0.0.0:8443`", name, value)
+}
+
+/// `host:port`, bracketing IPv6 literals so the port stays unambiguous
+pub fn host_port(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+/// A TCP listener on `addr`; see the module docs for `dual_stack`
+pub fn tcp_listener(addr: SocketAddr, dual_stack: bool) -> io::Result<tokio::net::TcpListener> {
+    let listener = with_fallback(addr, |addr| {
+        let socket = socket(addr, Type::STREAM, dual_stack)?;
+        socket.listen(BACKLOG)?;
+        Ok(std::net::TcpListener::from(socket))
+    })?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
+/// A UDP socket on `addr`; see the module docs for `dual_stack`
+pub fn udp_socket(addr: SocketAddr, dual_stack: bool) -> io::Result<tokio::net::UdpSocket> {
+    let socket = with_fallback(addr, |addr| Ok(std::net::UdpSocket::from(socket(addr, Type::DGRAM, dual_stack)?)))?;
+    tokio::net::UdpSocket::from_std(socket)
+}
+
+fn socket(addr: SocketAddr, kind: Type, dual_stack: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Bind `addr`, or `0.0.0.0` on the same port when `addr` is `::` and the
+/// host has no IPv6
+fn with_fallback<T>(addr: SocketAddr, bind: impl Fn(SocketAddr) -> io::Result<T>) -> io::Result<T> {
+    match bind(addr) {
+        Err(e) if addr.ip().is_unspecified() && addr.is_ipv6() => {
+            let fallback = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port());
+            let bound = bind(fallback).map_err(|_| e)?;
+            warn!("IPv6 is unavailable, listening on {} instead of {}", fallback, addr);
+            Ok(bound)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_round_trips() {
+        for (value, expected) in [("::", "::"), ("[::]", "::"), ("0.0.0.0", "0.0.0.0"), (" fd00::1 ", "fd00::1")] {
+            assert_eq!(parse_host("HOST", value).unwrap(), expected.parse::<IpAddr>().unwrap(), "{}", value);
+        }
+        for invalid in ["", "localhost", "[10.0.0.1]", "10.0.0.1:8443", "[::1"] {
+            assert!(parse_host("HOST", invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_host("HOST", "10.0.0.1:8443").unwrap_err().to_string().contains("port separately"));
+
+        for value in ["[::]:8443", "0.0.0.0:3000", "[fe80::1]:9"] {
+            let addr = parse_socket_addr("LEPTOS_SITE_ADDR", value).unwrap();
+            assert_eq!(addr.to_string(), value);
+            assert_eq!(host_port(&addr.ip().to_string(), addr.port()), value);
+        }
+        let error = parse_socket_addr("LEPTOS_SITE_ADDR", "::1").unwrap_err().to_string();
+        assert!(error.contains("need brackets"), "{}", error);
+        assert!(parse_socket_addr("LEPTOS_SITE_ADDR", ":::8443").is_err());
+
+        assert_eq!(host_port("relay.example.com", 443), "relay.example.com:443");
+        assert_eq!(host_port("2001:db8::7", 3478), "[2001:db8::7]:3478");
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listener_accepts_ipv4() {
+        let Ok(listener) = tcp_listener("[::]:0".parse().unwrap(), true) else { return };
+        let port = listener.local_addr().unwrap().port();
+        if listener.local_addr().unwrap().is_ipv4() {
+            // Fell back on a host without IPv6
+            return;
+        }
+
+        let (_client, accepted) = tokio::join!(
+            tokio::net::TcpStream::connect(("127.0.0.1", port)),
+            listener.accept(),
+        );
+        let (_, peer) = accepted.unwrap();
+        assert_eq!(peer.ip().to_canonical(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+}
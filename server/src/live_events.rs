@@ -0,0 +1,582 @@
+//! Live dashboard events
+//!
+//! `/api/ws/events` pushes device, session, backstage approval and relay
+//! node changes to the web dashboard as DeviceManager makes them, starting
+//! with a `Snapshot` so the page needs no separate REST call. A client narrows what it gets with a
+//! `subscribe` message naming a group and/or device IDs, answered by a fresh
+//! snapshot. Connections only hear about devices of their user's
+//! organization; relay node health goes to everyone.
+//!
+//! Publishing never waits on a subscriber. Each connection has a mailbox
+//! holding at most the latest pending event per device status, device
+//! metrics, session, approval request and relay node, so a slow browser sees fewer, newer
+//! events instead of holding DeviceManager up. A mailbox that still fills
+//! up is emptied and its connection sent a new snapshot instead.
+//!
+//! The server pings every [`PING_INTERVAL`] and closes connections it has
+//! heard nothing from, pongs included, for [`STALE_AFTER`].
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::api::admit;
+use crate::approvals::Approval;
+use crate::auth::jwt::AuthUser;
+use crate::device_groups::{DeviceListing, GroupFilter};
+use crate::device_manager::{DeviceManager, MetricsSample};
+use crate::models::Session;
+use crate::organizations::Tenant;
+use crate::rate_limit::ClientAddress;
+use crate::relay::RelayNode;
+use crate::AppState;
+
+/// How often the server pings an events connection
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Silence after which an events connection is closed as stale
+pub const STALE_AFTER: Duration = Duration::from_secs(90);
+
+/// Distinct pending events a mailbox holds before it resyncs
+const MAILBOX_CAPACITY: usize = 1024;
+
+/// A change pushed to dashboard clients
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum LiveEvent {
+    DeviceOnline {
+        device: DeviceListing,
+    },
+    DeviceOffline {
+        device_id: Uuid,
+        last_seen: Option<DateTime<Utc>>,
+    },
+    /// Still connected but missing heartbeats
+    DeviceDegraded {
+        device_id: Uuid,
+        last_seen: Option<DateTime<Utc>>,
+    },
+    /// Resource usage from a heartbeat
+    DeviceMetrics {
+        device_id: Uuid,
+        metrics: MetricsSample,
+    },
+    SessionStarted {
+        session: Session,
+    },
+    SessionEnded {
+        session: Session,
+    },
+    /// A backstage session was held for approval, or its request decided
+    BackstageApproval {
+        approval: Approval,
+    },
+    /// A relay node registered, heartbeated or started draining
+    RelayNodeHealth {
+        node: RelayNode,
+    },
+    /// A relay node was evicted after going silent
+    RelayNodeRemoved {
+        node_id: Uuid,
+    },
+}
+
+impl LiveEvent {
+    /// What a newer event replaces an older pending one for
+    fn key(&self) -> EventKey {
+        match self {
+            LiveEvent::DeviceOnline { device } => EventKey::DeviceStatus(device.agent.id),
+            LiveEvent::DeviceOffline { device_id, .. } | LiveEvent::DeviceDegraded { device_id, .. } => {
+                EventKey::DeviceStatus(*device_id)
+            }
+            LiveEvent::DeviceMetrics { device_id, .. } => EventKey::DeviceMetrics(*device_id),
+            LiveEvent::SessionStarted { session } | LiveEvent::SessionEnded { session } => {
+                EventKey::Session(session.id)
+            }
+            LiveEvent::BackstageApproval { approval } => EventKey::Approval(approval.id),
+            LiveEvent::RelayNodeHealth { node } => EventKey::RelayNode(node.id),
+            LiveEvent::RelayNodeRemoved { node_id } => EventKey::RelayNode(*node_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum EventKey {
+    DeviceStatus(Uuid),
+    DeviceMetrics(Uuid),
+    Session(Uuid),
+    Approval(Uuid),
+    RelayNode(Uuid),
+}
+
+/// The device an event concerns, for filtering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventScope {
+    Device { agent_id: Uuid, group_id: Option<Uuid>, organization_id: Option<Uuid> },
+    /// Not about any one device
+    Global,
+}
+
+impl EventScope {
+    /// Whether connections in `tenant` may hear about it
+    fn visible_to(&self, tenant: Tenant) -> bool {
+        match self {
+            EventScope::Device { organization_id, .. } => tenant.includes(*organization_id),
+            EventScope::Global => true,
+        }
+    }
+}
+
+/// What a connection wants to hear about; unset criteria match everything,
+/// and every criterion set must match
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Subscription {
+    /// Group ID, or `none` for devices in no group
+    #[serde(default, deserialize_with = "deserialize_group")]
+    pub group: Option<GroupFilter>,
+    #[serde(default)]
+    pub device_ids: Option<HashSet<Uuid>>,
+}
+
+fn deserialize_group<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<GroupFilter>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|group| group.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl Subscription {
+    pub fn matches(&self, scope: &EventScope) -> bool {
+        let EventScope::Device { agent_id, group_id, .. } = scope else {
+            return true;
+        };
+        self.matches_device(*agent_id, *group_id)
+    }
+
+    pub fn matches_device(&self, agent_id: Uuid, group_id: Option<Uuid>) -> bool {
+        let group_matches = match self.group {
+            None => true,
+            Some(GroupFilter::Group(group)) => group_id == Some(group),
+            Some(GroupFilter::Ungrouped) => group_id.is_none(),
+        };
+        group_matches && self.device_ids.as_ref().is_none_or(|ids| ids.contains(&agent_id))
+    }
+}
+
+/// State a connection starts from, limited to its subscription
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveSnapshot {
+    pub devices: Vec<DeviceListing>,
+    /// Active sessions
+    pub sessions: Vec<Session>,
+    /// Latest heartbeat metrics by device ID
+    pub metrics: HashMap<Uuid, MetricsSample>,
+    /// Backstage sessions waiting for approval
+    pub approvals: Vec<Approval>,
+    pub relay_nodes: Vec<RelayNode>,
+}
+
+/// What a mailbox hands its connection next
+#[derive(Debug, Clone)]
+pub enum Delivery {
+    Event(Box<LiveEvent>),
+    /// Events were lost to overflow; send a new snapshot
+    Resync,
+}
+
+#[derive(Debug, Default)]
+struct MailboxState {
+    /// Organization the connection's user works in
+    tenant: Tenant,
+    subscription: Subscription,
+    /// Pending keys, oldest first
+    order: VecDeque<EventKey>,
+    pending: HashMap<EventKey, LiveEvent>,
+    resync: bool,
+    /// Events replaced by newer ones before delivery
+    coalesced: u64,
+}
+
+/// One connection's pending events
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    state: Mutex<MailboxState>,
+    notify: Notify,
+}
+
+impl Mailbox {
+    fn offer_within(&self, scope: &EventScope, event: &LiveEvent, capacity: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.resync || !scope.visible_to(state.tenant) || !state.subscription.matches(scope) {
+            return;
+        }
+        let key = event.key();
+        if state.pending.insert(key, event.clone()).is_some() {
+            state.coalesced += 1;
+        } else if state.order.len() >= capacity {
+            // The snapshot that follows covers everything dropped here
+            state.order.clear();
+            state.pending.clear();
+            state.resync = true;
+        } else {
+            state.order.push_back(key);
+        }
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    fn offer(&self, scope: &EventScope, event: &LiveEvent) {
+        self.offer_within(scope, event, MAILBOX_CAPACITY);
+    }
+
+    /// Next delivery, if one is pending
+    pub fn take(&self) -> Option<Delivery> {
+        let mut state = self.state.lock().unwrap();
+        if state.resync {
+            state.resync = false;
+            return Some(Delivery::Resync);
+        }
+        let key = state.order.pop_front()?;
+        state.pending.remove(&key).map(|event| Delivery::Event(Box::new(event)))
+    }
+
+    /// Wait for the next delivery
+    pub async fn next(&self) -> Delivery {
+        loop {
+            if let Some(delivery) = self.take() {
+                return delivery;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Switch to `subscription`, dropping what is pending; the caller sends
+    /// a snapshot for the new subscription
+    pub fn resubscribe(&self, subscription: Subscription) {
+        let mut state = self.state.lock().unwrap();
+        state.subscription = subscription;
+        state.order.clear();
+        state.pending.clear();
+        state.resync = false;
+    }
+
+    pub fn subscription(&self) -> Subscription {
+        self.state.lock().unwrap().subscription.clone()
+    }
+
+    pub fn tenant(&self) -> Tenant {
+        self.state.lock().unwrap().tenant
+    }
+
+    /// Events replaced by newer ones before they were delivered
+    pub fn coalesced(&self) -> u64 {
+        self.state.lock().unwrap().coalesced
+    }
+}
+
+/// Fans DeviceManager's changes out to the events connections
+#[derive(Debug, Default)]
+pub struct EventHub {
+    subscribers: Mutex<Vec<Weak<Mailbox>>>,
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mailbox for a new connection in `tenant`; it is dropped from the hub
+    /// with the last reference to it
+    pub fn subscribe(&self, tenant: Tenant, subscription: Subscription) -> Arc<Mailbox> {
+        let mailbox = Arc::new(Mailbox::default());
+        mailbox.state.lock().unwrap().tenant = tenant;
+        mailbox.resubscribe(subscription);
+        self.subscribers.lock().unwrap().push(Arc::downgrade(&mailbox));
+        mailbox
+    }
+
+    /// Hand `event` to every matching subscriber without waiting on any
+    pub fn publish(&self, scope: EventScope, event: LiveEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| match subscriber.upgrade() {
+            Some(mailbox) => {
+                mailbox.offer(&scope, &event);
+                true
+            }
+            None => false,
+        });
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+        subscribers.len()
+    }
+}
+
+/// Message a client sends on the events socket
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(Subscription),
+    Ping,
+}
+
+/// WebSocket of live dashboard events for a signed-in user
+pub async fn websocket_events_handler(
+    ws: WebSocketUpgrade,
+    State(app_state): State<AppState>,
+    ClientAddress(address): ClientAddress,
+    user: AuthUser,
+    tenant: Tenant,
+) -> Response {
+    let permit = app_state.device_manager.rate_limiter.acquire_connection(address);
+    ws.on_upgrade(move |socket| async move {
+        let Some((socket, _permit)) = admit(socket, permit).await else { return };
+        info!("Events WebSocket connected for user {}", user.user_id);
+        run_events_connection(socket, app_state.device_manager, tenant).await;
+        info!("Events WebSocket disconnected for user {}", user.user_id);
+    })
+}
+
+async fn run_events_connection(socket: WebSocket, device_manager: Arc<DeviceManager>, tenant: Tenant) {
+    let (mut sender, mut receiver) = socket.split();
+    let mailbox = device_manager.live_events.subscribe(tenant, Subscription::default());
+    if send_snapshot(&mut sender, &device_manager, &mailbox).await.is_err() {
+        return;
+    }
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.tick().await;
+    let mut last_heard = tokio::time::Instant::now();
+    loop {
+        tokio::select! {
+            delivery = mailbox.next() => {
+                let sent = match delivery {
+                    Delivery::Event(event) => send_json(&mut sender, &event).await,
+                    Delivery::Resync => {
+                        debug!("Events connection fell behind; sending a new snapshot");
+                        send_snapshot(&mut sender, &device_manager, &mailbox).await
+                    }
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+            message = receiver.next() => {
+                let Some(Ok(message)) = message else { break };
+                last_heard = tokio::time::Instant::now();
+                let Message::Text(text) = message else { continue };
+                match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(ClientMessage::Subscribe(subscription)) => {
+                        mailbox.resubscribe(subscription);
+                        if send_snapshot(&mut sender, &device_manager, &mailbox).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(ClientMessage::Ping) => {
+                        if send_json(&mut sender, &serde_json::json!({ "type": "Pong" })).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let error = serde_json::json!({ "type": "Error", "error": e.to_string() });
+                        if send_json(&mut sender, &error).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                if last_heard.elapsed() >= STALE_AFTER {
+                    warn!("Closing events connection silent for {}s", last_heard.elapsed().as_secs());
+                    break;
+                }
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = sender.close().await;
+}
+
+async fn send_snapshot<S>(sender: &mut S, device_manager: &DeviceManager, mailbox: &Mailbox) -> Result<(), axum::Error>
+where
+    S: futures_util::Sink<Message, Error = axum::Error> + Unpin,
+{
+    let snapshot = device_manager.live_snapshot(mailbox.tenant(), &mailbox.subscription()).await;
+    let mut message = serde_json::to_value(&snapshot).unwrap_or_default();
+    message["type"] = "Snapshot".into();
+    send_json(sender, &message).await
+}
+
+async fn send_json<S, T>(sender: &mut S, message: &T) -> Result<(), axum::Error>
+where
+    S: futures_util::Sink<Message, Error = axum::Error> + Unpin,
+    T: Serialize,
+{
+    let text = serde_json::to_string(message).map_err(axum::Error::new)?;
+    sender.send(Message::Text(text)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_scope(agent_id: Uuid, group_id: Option<Uuid>) -> EventScope {
+        EventScope::Device { agent_id, group_id, organization_id: None }
+    }
+
+    fn offline(device_id: Uuid) -> LiveEvent {
+        LiveEvent::DeviceOffline { device_id, last_seen: None }
+    }
+
+    fn removed(node_id: Uuid) -> LiveEvent {
+        LiveEvent::RelayNodeRemoved { node_id }
+    }
+
+    fn delivered(mailbox: &Mailbox) -> Vec<Uuid> {
+        std::iter::from_fn(|| mailbox.take())
+            .map(|delivery| match delivery {
+                Delivery::Event(event) => match *event {
+                    LiveEvent::DeviceOffline { device_id, .. } => device_id,
+                    LiveEvent::RelayNodeRemoved { node_id } => node_id,
+                    other => panic!("unexpected event {:?}", other),
+                },
+                Delivery::Resync => panic!("unexpected resync"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_subscription_filtering() {
+        let group = Uuid::new_v4();
+        let (grouped, ungrouped, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let hub = EventHub::new();
+        let everything = hub.subscribe(Tenant::All, Subscription::default());
+        let by_group = hub.subscribe(Tenant::All, Subscription { group: Some(GroupFilter::Group(group)), device_ids: None });
+        let by_device = hub.subscribe(Tenant::All, Subscription { group: None, device_ids: Some(HashSet::from([ungrouped])) });
+        let no_group = hub.subscribe(Tenant::All, Subscription { group: Some(GroupFilter::Ungrouped), device_ids: None });
+
+        hub.publish(device_scope(grouped, Some(group)), offline(grouped));
+        hub.publish(device_scope(ungrouped, None), offline(ungrouped));
+        hub.publish(device_scope(other, Some(Uuid::new_v4())), offline(other));
+        let node = Uuid::new_v4();
+        hub.publish(EventScope::Global, removed(node));
+
+        assert_eq!(delivered(&everything), vec![grouped, ungrouped, other, node]);
+        assert_eq!(delivered(&by_group), vec![grouped, node]);
+        assert_eq!(delivered(&by_device), vec![ungrouped, node]);
+        assert_eq!(delivered(&no_group), vec![ungrouped, node]);
+
+        // Both criteria must match
+        let both = Subscription { group: Some(GroupFilter::Group(group)), device_ids: Some(HashSet::from([ungrouped])) };
+        assert!(!both.matches(&device_scope(ungrouped, None)));
+        assert!(!both.matches(&device_scope(grouped, Some(group))));
+        assert!(both.matches(&device_scope(ungrouped, Some(group))));
+
+        // A new subscription drops what was pending under the old one
+        hub.publish(device_scope(grouped, Some(group)), offline(grouped));
+        by_device.resubscribe(Subscription { group: None, device_ids: Some(HashSet::from([grouped])) });
+        assert!(by_device.take().is_none());
+        hub.publish(device_scope(grouped, Some(group)), offline(grouped));
+        assert_eq!(delivered(&by_device), vec![grouped]);
+
+        let message: Subscription = serde_json::from_value(serde_json::json!({ "group": "none" })).unwrap();
+        assert_eq!(message.group, Some(GroupFilter::Ungrouped));
+        assert!(serde_json::from_value::<Subscription>(serde_json::json!({ "group": "staff" })).is_err());
+    }
+
+    #[test]
+    fn test_events_stay_in_their_organization() {
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+        let hub = EventHub::new();
+        let everything = hub.subscribe(Tenant::All, Subscription::default());
+        let acme_only = hub.subscribe(Tenant::Organization(Some(acme)), Subscription::default());
+        let unassigned = hub.subscribe(Tenant::Organization(None), Subscription::default());
+
+        let (acme_device, globex_device) = (Uuid::new_v4(), Uuid::new_v4());
+        let scope = |agent_id, organization_id| EventScope::Device { agent_id, group_id: None, organization_id };
+        hub.publish(scope(acme_device, Some(acme)), offline(acme_device));
+        hub.publish(scope(globex_device, Some(globex)), offline(globex_device));
+        let node = Uuid::new_v4();
+        hub.publish(EventScope::Global, removed(node));
+
+        assert_eq!(delivered(&everything), vec![acme_device, globex_device, node]);
+        assert_eq!(delivered(&acme_only), vec![acme_device, node]);
+        assert_eq!(delivered(&unassigned), vec![node]);
+    }
+
+    #[test]
+    fn test_events_coalesce_per_device() {
+        let hub = EventHub::new();
+        let mailbox = hub.subscribe(Tenant::All, Subscription::default());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        for seen in 0..5 {
+            let last_seen = Some(DateTime::from_timestamp(seen, 0).unwrap());
+            hub.publish(device_scope(first, None), LiveEvent::DeviceOffline { device_id: first, last_seen });
+        }
+        hub.publish(device_scope(second, None), offline(second));
+
+        // The first device's five changes arrive as its latest one, in its
+        // original place
+        match mailbox.take() {
+            Some(Delivery::Event(event)) => match *event {
+                LiveEvent::DeviceOffline { device_id, last_seen } => {
+                    assert_eq!(device_id, first);
+                    assert_eq!(last_seen, DateTime::from_timestamp(4, 0));
+                }
+                other => panic!("unexpected event {:?}", other),
+            },
+            other => panic!("unexpected delivery {:?}", other),
+        }
+        assert_eq!(delivered(&mailbox), vec![second]);
+        assert_eq!(mailbox.coalesced(), 4);
+    }
+
+    #[test]
+    fn test_overflow_resyncs_instead_of_blocking() {
+        let mailbox = Mailbox::default();
+        for _ in 0..4 {
+            let device = Uuid::new_v4();
+            mailbox.offer_within(&device_scope(device, None), &offline(device), 3);
+        }
+        assert!(matches!(mailbox.take(), Some(Delivery::Resync)));
+        assert!(mailbox.take().is_none());
+
+        // Events published after the resync are delivered again
+        let device = Uuid::new_v4();
+        mailbox.offer_within(&device_scope(device, None), &offline(device), 3);
+        assert_eq!(delivered(&mailbox), vec![device]);
+    }
+
+    #[tokio::test]
+    async fn test_closed_connections_leave_the_hub() {
+        let hub = EventHub::new();
+        let mailbox = hub.subscribe(Tenant::All, Subscription::default());
+        let waiting = {
+            let mailbox = mailbox.clone();
+            tokio::spawn(async move { mailbox.next().await })
+        };
+        hub.publish(EventScope::Global, removed(Uuid::new_v4()));
+        assert!(matches!(waiting.await.unwrap(), Delivery::Event(event) if matches!(*event, LiveEvent::RelayNodeRemoved { .. })));
+
+        assert_eq!(hub.subscriber_count(), 1);
+        drop(mailbox);
+        assert_eq!(hub.subscriber_count(), 0);
+    }
+}
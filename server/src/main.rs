@@ -1,5 +1,5 @@
 use axum::{
-    extract::State,
+    extract::{DefaultBodyLimit, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::{delete, get, post, put},
@@ -18,6 +18,7 @@ use tower_http::{
 use tracing::info;
 
 mod api;
+mod audit;
 mod config;
 mod database;
 mod models;
@@ -29,17 +30,46 @@ mod branding;
 mod direct_connect;
 mod vpn_integration;
 mod auth {
+    pub mod access_grants;
+    pub mod authz;
+    pub mod enrollment;
+    pub mod invitations;
     pub mod jwt;
     pub mod oidc;
+    pub mod session_tokens;
 }
 mod pam;
+mod registry;
+mod file_browser;
+mod file_drop;
+mod releases;
+mod diagnostics;
+mod crash_reports;
 mod terminal;
+mod session_events;
+mod session_transfers;
+mod approvals;
+mod listen;
+mod live_events;
+mod wake;
+mod support_codes;
+mod deployments;
+mod device_groups;
+mod policies;
+mod organizations;
+mod rate_limit;
+mod telemetry;
+mod webhooks;
+mod recordings;
+mod device_records;
+mod session_notes;
 
 use crate::{
     config::AppConfig,
     web::app::App,
     device_manager::DeviceManager,
     database::DatabaseService,
+    auth::enrollment::EnrollmentService,
 };
 
 // Application state for the server
@@ -48,6 +78,9 @@ pub struct AppState {
     pub device_manager: Arc<DeviceManager>,
     pub config: AppConfig,
     pub db: Option<Arc<DatabaseService>>,
+    pub enrollment: Arc<EnrollmentService>,
+    /// Series scraped from `/metrics`, shared with the device manager
+    pub metrics: Arc<telemetry::Metrics>,
 }
 
 #[tokio::main]
@@ -59,21 +92,238 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Load configuration
     let config = AppConfig::load()?;
-    info!("Starting AtlasConnect Server on {}:{}", config.host, config.port);
+    info!("Starting AtlasConnect Server on {}", listen::host_port(&config.host, config.port));
+
+    // Connect to PostgreSQL when configured; otherwise state lives in memory only
+    let db = match &config.database_url {
+        Some(database_url) => match DatabaseService::connect(database_url).await {
+            Ok(db) => {
+                info!("Connected to database");
+                Some(Arc::new(db))
+            }
+            Err(e) => {
+                eprintln!("Failed to connect to database: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            info!("DATABASE_URL not set, keeping devices and sessions in memory");
+            None
+        }
+    };
 
     // Initialize app state
-    let device_manager = Arc::new(DeviceManager::new());
+    let metrics = Arc::new(telemetry::Metrics::new());
+    let mut device_manager = DeviceManager::new()
+        .with_telemetry(metrics.clone())
+        .with_session_policy(config.session_policy.clone())
+        .with_input_window(std::time::Duration::from_millis(config.input_coalesce_ms))
+        .with_compression(config.compression.clone())
+        .with_session_token_secret(&config.jwt_secret)
+        .with_access_grant_secret(&config.jwt_secret)
+        .with_idle_policy(config.idle.clone())
+        .with_liveness_policy(config.liveness.clone())
+        .with_broker_policy(config.relay_broker.clone())
+        .with_rate_limits(config.rate_limits.clone());
+    if let Some(port) = config.relay_udp_port {
+        let addr = std::net::SocketAddr::new(config.host_ip()?, port);
+        match listen::udp_socket(addr, config.dual_stack).and_then(relay::udp_lane::UdpLane::from_socket) {
+            Ok(lane) => device_manager = device_manager.with_udp_lane(Arc::new(lane)),
+            Err(e) => {
+                eprintln!("Failed to bind the UDP lane on port {}: {}", port, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    match &db {
+        Some(db) => device_manager = device_manager.with_database(db.clone()),
+        None => {
+            info!("Keeping the audit trail in {}", config.audit_file.dir.display());
+            device_manager = device_manager.with_audit_file(Arc::new(audit::AuditFile::new(&config.audit_file)));
+        }
+    }
+    let mut webhooks = webhooks::WebhookManager::new(config.webhooks.clone());
+    if let Some(db) = &db {
+        webhooks = webhooks.with_database(db.clone());
+    }
+    device_manager = device_manager.with_webhooks(webhooks);
+    let recording_store = match recordings::storage::open(&config.recordings.storage) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Failed to open recording storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut recording_manager = recordings::RecordingManager::new(Arc::from(recording_store))
+        .with_default_retention(config.recordings.retention_days);
+    if let Some(db) = &db {
+        recording_manager = recording_manager.with_database(db.clone());
+    }
+    device_manager = device_manager.with_recordings(recording_manager);
+    let mut device_records = device_records::DeviceRecords::new();
+    if let Some(db) = &db {
+        device_records = device_records.with_database(db.clone());
+    }
+    device_manager = device_manager.with_device_records(device_records);
+    let mut session_notes = session_notes::SessionNotes::new();
+    if let Some(db) = &db {
+        session_notes = session_notes.with_database(db.clone());
+    }
+    device_manager = device_manager.with_session_notes(session_notes);
+    let mut crash_reports = crash_reports::CrashReportStore::new();
+    if let Some(db) = &db {
+        crash_reports = crash_reports.with_database(db.clone());
+    }
+    device_manager = device_manager.with_crash_reports(crash_reports);
+    let device_manager = Arc::new(device_manager);
+
+    let mut enrollment = EnrollmentService::new(&config.jwt_secret, config.allow_unauthenticated_agents);
+    if let Some(db) = &db {
+        enrollment = enrollment.with_database(db.clone());
+    }
+    if config.allow_unauthenticated_agents {
+        tracing::warn!("ALLOW_UNAUTHENTICATED_AGENTS is set: agents that never enrolled can still connect");
+    }
     
     // Initialize all managers
     if let Err(e) = device_manager.initialize().await {
         eprintln!("Failed to initialize device manager: {}", e);
         std::process::exit(1);
     }
+
+    // Deliver webhook events, retrying endpoints that failed
+    tokio::spawn(device_manager.webhooks.clone().run());
+
+    // Purge temporary agents whose support codes expired before anyone connected
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(support_codes::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.expire_support_codes(chrono::Utc::now()).await;
+        }
+    });
+
+    // Start scheduled deployments and give up on devices that never report back
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(deployments::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            deployments::run_due(&sweeper, chrono::Utc::now()).await;
+        }
+    });
+
+    // Settle takeover requests the controlling technician never answered
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(relay::control::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.expire_control_requests(chrono::Utc::now()).await;
+        }
+    });
+
+    // Withdraw session transfers nobody took up in time
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(session_transfers::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.expire_transfers(chrono::Utc::now()).await;
+        }
+    });
+
+    // Expire backstage requests nobody approved in time
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(approvals::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.expire_approvals(chrono::Utc::now()).await;
+        }
+    });
+
+    // End sessions guests opened under access grants that expired
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(auth::access_grants::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.expire_access_grants(chrono::Utc::now()).await;
+        }
+    });
+
+    // Degrade and disconnect devices that stopped heartbeating
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(relay::liveness::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.sweep_liveness(chrono::Utc::now()).await;
+        }
+    });
+
+    // End sessions nobody has touched for longer than the idle timeout
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(relay::idle::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.expire_idle_sessions(chrono::Utc::now()).await;
+        }
+    });
+    
+    if let Some(lane) = device_manager.udp_lane.clone() {
+        tokio::spawn(relay::udp_lane::run(lane, device_manager.clone()));
+    }
+
+    // Forget where devices were reachable once their heartbeats stop
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(relay::rendezvous::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.rendezvous.expire(chrono::Utc::now()).await;
+        }
+    });
+
+    // Drop refilled login buckets and failures too old to matter
+    let sweeper = device_manager.rate_limiter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(rate_limit::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.sweep();
+        }
+    });
+
+    // Delete session recordings past their retention
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(recordings::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.expire_recordings(chrono::Utc::now()).await;
+        }
+    });
+
+    // Stop placing sessions on relay nodes that went quiet, then forget them
+    let sweeper = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(relay::connection_broker::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.sweep_relay_nodes(chrono::Utc::now()).await;
+        }
+    });
     
     let app_state = AppState {
         device_manager,
         config: config.clone(),
-        db: None, // Database connection is optional, set via DATABASE_URL if needed
+        db,
+        enrollment: Arc::new(enrollment),
+        metrics,
     };
 
     // Setting get_configuration(None) means we'll be using cargo-leptos's env values
@@ -81,7 +331,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // <https://github.com/leptos-rs/start-axum#executing-a-server-on-a-remote-machine-without-the-toolchain>
     // Alternately a file can be specified such as Some("Cargo.toml")
     // The file would need to be included with the executable when moved to deployment
-    let conf = get_configuration(None).await.unwrap();
+    let conf = get_configuration(None).await?;
     let leptos_options = conf.leptos_options;
     let addr = leptos_options.site_addr;
     let routes = generate_route_list(App);
@@ -91,6 +341,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/ws", get(api::websocket_device_handler))
         .route("/health", get(api::health_check))
         .route("/register", post(api::api_register_device))
+        .route("/enroll", post(auth::enrollment::api_enroll_device))
+        .route_layer(axum::middleware::from_fn_with_state(
+            app_state.metrics.clone(),
+            telemetry::track_requests,
+        ))
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
@@ -98,30 +353,175 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .with_state(app_state.clone());
 
+    // Routes that take credentials draw from each client address's bucket
+    let throttled_auth_routes = Router::new()
+        .route("/api/auth/login", post(auth::jwt::endpoints::login))
+        .route("/api/auth/refresh", post(auth::jwt::endpoints::refresh))
+        .route("/api/auth/guest", get(auth::access_grants::api_guest_login))
+        .route("/api/auth/oidc/login", get(auth::oidc::api_oidc_login))
+        .route("/api/auth/oidc/callback", get(auth::oidc::api_oidc_callback))
+        .route("/api/auth/oidc/oauth-callback", get(auth::oidc::api_oauth_callback))
+        .route_layer(axum::middleware::from_fn_with_state(
+            app_state.device_manager.rate_limiter.clone(),
+            rate_limit::throttle_auth,
+        ));
+
     // Build API routes
     let api_routes = Router::new()
         // Authentication routes (public)
-        .route("/api/auth/login", post(auth::jwt::endpoints::login))
-        .route("/api/auth/refresh", post(auth::jwt::endpoints::refresh))
+        .merge(throttled_auth_routes)
         .route("/api/auth/logout", post(auth::jwt::endpoints::logout))
         .route("/api/auth/me", get(auth::jwt::endpoints::me))
+        .route("/api/auth/switch-organization", post(organizations::api_switch_organization))
         
         // Device management routes (protected)
         .route("/api/devices", get(api::api_get_devices))
+        .route("/api/devices/enroll-tokens", post(auth::enrollment::api_create_enroll_token))
+        .route("/api/invitations", get(auth::invitations::api_list_invitations))
+        .route("/api/invitations", post(auth::invitations::api_create_invitation))
+        .route("/api/invitations/:id", delete(auth::invitations::api_revoke_invitation))
+        .route("/api/devices/:id", get(api::api_get_device))
+        .route("/api/devices/:id/metrics", get(api::api_get_device_metrics))
         .route("/api/devices/:id/sessions", get(api::api_get_device_sessions))
         .route("/api/devices/:id/sessions", post(api::api_create_session))
+        .route("/api/devices/:id/enrollment", delete(auth::enrollment::api_reset_enrollment))
+        .route("/api/devices/:id/grants", get(auth::access_grants::api_list_device_access_grants))
+        .route("/api/devices/:id/grants", post(auth::access_grants::api_create_access_grant))
+        .route("/api/grants", get(auth::access_grants::api_list_access_grants))
+        .route("/api/grants/:id", delete(auth::access_grants::api_revoke_access_grant))
+        .route("/api/devices/:id/wake", post(wake::api_wake_device))
+        .route("/api/devices/:id/group", put(device_groups::api_set_device_group))
+        .route("/api/devices/:id/tags", put(device_groups::api_set_device_tags))
+        .route("/api/devices/:id/alias", put(device_groups::api_set_device_alias))
+        .route("/api/devices/:id/notes", get(device_records::api_get_device_notes))
+        .route("/api/devices/:id/notes", put(device_records::api_set_device_notes))
+        .route("/api/devices/:id/fields", get(device_records::api_get_device_fields))
+        .route("/api/devices/:id/fields", put(device_records::api_set_device_fields))
+        .route("/api/devices/:id/inventory", get(device_records::api_get_device_inventory))
+        .route("/api/groups", get(device_groups::api_list_groups))
+        .route("/api/groups", post(device_groups::api_create_group))
+        .route("/api/groups/:id", get(device_groups::api_get_group))
+        .route("/api/groups/:id", put(device_groups::api_update_group))
+        .route("/api/groups/:id", delete(device_groups::api_delete_group))
+        .route("/api/custom-fields", get(device_records::api_list_custom_fields))
+        .route("/api/custom-fields", post(device_records::api_create_custom_field))
+        .route("/api/custom-fields/:id", put(device_records::api_update_custom_field))
+        .route("/api/custom-fields/:id", delete(device_records::api_delete_custom_field))
+        .route("/api/note-templates", get(session_notes::api_list_note_templates))
+        .route("/api/note-templates", post(session_notes::api_create_note_template))
+        .route("/api/note-templates/:id", put(session_notes::api_update_note_template))
+        .route("/api/note-templates/:id", delete(session_notes::api_delete_note_template))
+        .route("/api/policies", get(policies::api_list_policies))
+        .route("/api/policies", post(policies::api_create_policy))
+        .route("/api/policies/:id", get(policies::api_get_policy))
+        .route("/api/policies/:id", put(policies::api_update_policy))
+        .route("/api/policies/:id", delete(policies::api_delete_policy))
+        .route("/api/webhooks", get(webhooks::api_list_webhooks))
+        .route("/api/webhooks", post(webhooks::api_create_webhook))
+        .route("/api/webhooks/:id", get(webhooks::api_get_webhook))
+        .route("/api/webhooks/:id", put(webhooks::api_update_webhook))
+        .route("/api/webhooks/:id", delete(webhooks::api_delete_webhook))
+        .route("/api/webhooks/:id/deliveries", get(webhooks::api_webhook_deliveries))
+        .route("/api/webhooks/:id/test", post(webhooks::api_test_webhook))
+        .route("/api/devices/:id/policy", get(policies::api_device_policy))
+        .route("/api/organizations", get(organizations::api_list_organizations))
+        .route("/api/organizations", post(organizations::api_create_organization))
+        .route("/api/organizations/:id", get(organizations::api_get_organization))
+        .route("/api/organizations/:id", put(organizations::api_update_organization))
+        .route("/api/organizations/:id", delete(organizations::api_delete_organization))
+        .route("/api/organizations/:id/members/:user_id", put(organizations::api_add_member))
+        .route("/api/organizations/:id/members/:user_id", delete(organizations::api_remove_member))
+        .route("/api/sessions", get(api::api_list_sessions))
+        .route("/api/sessions/:id", get(api::api_get_session))
         .route("/api/sessions/:id", delete(api::api_end_session))
+        .route("/api/sessions/:id/quality", get(api::api_get_session_quality))
+        .route("/api/sessions/:id/commands", get(api::api_get_session_commands))
+        .route("/api/sessions/:id/commands", post(api::api_record_session_command))
+        .route("/api/sessions/:id/events", get(api::api_get_session_events))
+        .route("/api/sessions/:id/events", post(api::api_record_session_events))
+        .route("/api/sessions/:id/notes", get(session_notes::api_list_session_notes))
+        .route("/api/sessions/:id/notes", post(session_notes::api_add_session_note))
+        .route("/api/sessions/:id/notes/:note_id", put(session_notes::api_edit_session_note))
+        .route("/api/sessions/:id/ticket", put(session_notes::api_set_ticket_ref))
+        .route("/api/sessions/:id/fs", get(file_browser::api_list_directory))
+        .route("/api/sessions/:id/fs/archive", post(api::api_collect_archive))
+        .route("/api/sessions/:id/files", post(file_drop::api_upload_files)
+            .layer(DefaultBodyLimit::max(file_drop::MAX_DROP_REQUEST_SIZE)))
+        .route("/api/sessions/:id/registry/key", get(registry::api_registry_get_key))
+        .route("/api/sessions/:id/registry/key", post(registry::api_registry_create_key))
+        .route("/api/sessions/:id/registry/key", delete(registry::api_registry_delete_key))
+        .route("/api/sessions/:id/registry/value", put(registry::api_registry_set_value))
+        .route("/api/sessions/:id/registry/value", delete(registry::api_registry_delete_value))
+        .route("/api/sessions/:id/registry/export", get(registry::api_registry_export))
+        .route("/api/sessions/:id/transfer", post(session_transfers::api_offer_transfer))
         .route("/api/stats", get(api::api_get_stats))
+
+        // Handing sessions to another technician
+        .route("/api/transfers", get(session_transfers::api_list_transfers))
+        .route("/api/transfers/:id/accept", post(session_transfers::api_accept_transfer))
+        .route("/api/transfers/:id", delete(session_transfers::api_cancel_transfer))
+
+        // Backstage sessions waiting for a second person's approval
+        .route("/api/approvals", get(approvals::api_list_approvals))
+        .route("/api/approvals/:id", get(approvals::api_get_approval))
+        .route("/api/approvals/:id/approve", post(approvals::api_approve))
+        .route("/api/approvals/:id/deny", post(approvals::api_deny))
+
+        // Finding devices by ID or alias wherever they connect from
+        .route("/api/rendezvous/resolve", post(relay::rendezvous::api_resolve_device))
+
+        // Relay nodes sessions are brokered onto
+        .route("/api/relay-nodes", get(relay::connection_broker::api_list_relay_nodes))
+        .route("/api/relay-nodes/register", post(relay::connection_broker::api_register_relay_node))
+        .route("/api/relay-nodes/heartbeat", post(relay::connection_broker::api_relay_node_heartbeat))
+
+        // Ad-hoc support codes
+        .route("/api/support-codes", get(support_codes::api_list_support_codes))
+        .route("/api/support-codes", post(support_codes::api_create_support_code))
+        .route("/api/support-codes/:code", get(support_codes::api_get_support_code))
+        .route("/api/support-codes/:code/session", post(support_codes::api_connect_support_code))
+
+        // Agent auto-update
+        .route("/api/releases", post(releases::api_upload_release)
+            .layer(DefaultBodyLimit::max(releases::MAX_RELEASE_SIZE)))
+        .route("/api/releases/latest", get(releases::api_get_latest_release))
+        .route("/api/releases/:platform/:arch/:version/binary", get(releases::api_download_release))
+        
+        // Diagnostics bundles from `ghostlink-client logs upload`
+        .route("/api/devices/:id/diagnostics", post(diagnostics::api_upload_diagnostics)
+            .layer(DefaultBodyLimit::max(diagnostics::MAX_BUNDLE_SIZE)))
+        .route("/api/devices/:id/diagnostics", get(diagnostics::api_list_diagnostics))
+        .route("/api/devices/:id/diagnostics/:name", get(diagnostics::api_download_diagnostics))
+
+        // Session recordings uploaded by agents
+        .route("/api/recordings", post(recordings::api_begin_upload))
+        .route("/api/recordings", get(recordings::api_list_recordings))
+        .route("/api/recordings/:id/chunks/:index", put(recordings::api_upload_chunk)
+            .layer(DefaultBodyLimit::max(recordings::MAX_CHUNK_SIZE)))
+        .route("/api/recordings/:id", get(recordings::api_get_recording_meta))
+        .route("/api/recordings/:id/stream", get(recordings::api_stream_recording))
+
+        // Crash reports uploaded by agents
+        .route("/api/crash-reports", post(crash_reports::api_upload_crash_report)
+            .layer(DefaultBodyLimit::max(crash_reports::MAX_REPORT_SIZE)))
+        .route("/api/crash-reports", get(crash_reports::api_list_crash_reports))
+        .route("/api/crash-reports/groups", get(crash_reports::api_crash_report_groups))
+        .route("/api/crash-reports/:id", get(crash_reports::api_get_crash_report))
         .route("/api/ws", get(api::websocket_session_handler))
+        .route("/api/ws/events", get(live_events::websocket_events_handler))
+        .route("/api/session-tokens/key", get(auth::session_tokens::api_session_token_key))
         
         // Toolbox API routes
         .route("/api/toolbox/tools", get(toolbox::api_get_tools))
         .route("/api/toolbox/available", get(toolbox::api_get_available_tools))
+        .route("/api/toolbox/download/:id", get(toolbox::api_download_tool))
         .route("/api/toolbox/tools/:category", get(toolbox::api_get_tools_by_category))
         .route("/api/toolbox/execute", post(toolbox::api_execute_tool))
         .route("/api/toolbox/upload", post(toolbox::api_upload_tool))
         .route("/api/toolbox/upload-custom", post(toolbox::api_upload_custom_tool))
         .route("/api/toolbox/history", get(toolbox::api_get_execution_history))
+        .route("/api/toolbox/deploy", post(deployments::api_deploy))
+        .route("/api/toolbox/deployments/:id", get(deployments::api_get_deployment).delete(deployments::api_cancel_deployment))
         
         // Branding API routes
         .route("/api/branding/config", get(branding::api_get_branding_config))
@@ -146,9 +546,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/api/vpn/config", put(vpn_integration::api_update_vpn_config))
         
         // OIDC Authentication API routes
-        .route("/api/auth/oidc/login", get(auth::oidc::api_oidc_login))
-        .route("/api/auth/oidc/callback", get(auth::oidc::api_oidc_callback))
-        .route("/api/auth/oidc/oauth-callback", get(auth::oidc::api_oauth_callback))
         .route("/api/auth/oidc/auth-url", get(auth::oidc::api_get_auth_url))
         .route("/api/auth/oidc/validate", get(auth::oidc::api_validate_session))
         .route("/api/auth/oidc/logout", post(auth::oidc::api_logout))
@@ -172,6 +569,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/api/terminal/history", get(terminal::api_get_command_history))
         .route("/api/terminal/config", get(terminal::api_get_terminal_config))
         
+        // Audit trail of privileged actions
+        .route("/api/audit", get(audit::api_list_audit))
+        
+        // Prometheus scrape endpoint, guarded by METRICS_TOKEN rather than a login
+        .route("/metrics", get(telemetry::api_metrics))
+        
+        // Role-based authorization for every API route
+        .route_layer(axum::middleware::from_fn(auth::authz::require_route_permission))
+        // Latency by route template, including requests authorization turned away
+        .route_layer(axum::middleware::from_fn_with_state(
+            app_state.metrics.clone(),
+            telemetry::track_requests,
+        ))
         .with_state(app_state.clone());
 
     // Build web GUI routes (for atlas.cktechx.com - admin interface)
@@ -194,7 +604,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .merge(api_routes.with_state(())) // API routes with AppState
         .merge(web_routes.with_state(())); // Web routes with LeptosOptions
 
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let listener = listen::tcp_listener(addr, config.dual_stack)?;
+    let addr = listener.local_addr()?;
     info!("🚀 AtlasConnect Server listening on http://{}", &addr);
     info!("📡 Relay endpoints: /relay/* (for clients)");
     info!("🌐 Web GUI: /* (for admins)");
@@ -202,7 +613,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("   - relay.cktechx.com → proxy_pass to /relay/*");
     info!("   - atlas.cktechx.com → proxy_pass to /*");
 
-    axum::serve(listener, app.into_make_service())
+    // Peer addresses let audit entries name clients that reach us without a proxy
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .await
         .unwrap();
 
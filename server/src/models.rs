@@ -4,6 +4,8 @@ use chrono::{DateTime, Utc};
 use sqlx::{FromRow, Type};
 use std::collections::HashMap;
 
+use crate::relay::capabilities::AgentCapabilities;
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
     pub id: Uuid,
@@ -14,6 +16,14 @@ pub struct User {
     pub role: String,
     pub is_active: bool,
     pub last_login: Option<DateTime<Utc>>,
+    /// Failed logins since the last successful one
+    pub failed_login_attempts: i32,
+    /// Logins are refused until then
+    pub locked_until: Option<DateTime<Utc>>,
+    /// Subject of the OIDC identity the user was created from
+    pub oidc_subject: Option<String>,
+    /// May approve held backstage sessions without being an admin
+    pub is_approver: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -28,13 +38,73 @@ pub struct Organization {
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(rename_all = "lowercase")]
 #[sqlx(type_name = "VARCHAR", rename_all = "lowercase")]
 pub enum UserRole {
     Admin,
     Operator,
+    Technician,
     User,
     Viewer,
+    /// Holder of an access grant, confined to the granted device
+    Guest,
+}
+
+impl UserRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::Admin => "admin",
+            UserRole::Operator => "operator",
+            UserRole::Technician => "technician",
+            UserRole::User => "user",
+            UserRole::Viewer => "viewer",
+            UserRole::Guest => "guest",
+        }
+    }
+
+    /// Whether the role may manage users and server configuration
+    pub fn is_admin(&self) -> bool {
+        matches!(self, UserRole::Admin)
+    }
+
+    /// Whether the role may start, control and end remote sessions
+    pub fn can_control_sessions(&self) -> bool {
+        matches!(self, UserRole::Admin | UserRole::Operator | UserRole::Technician)
+    }
+}
+
+impl std::fmt::Display for UserRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for UserRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "admin" => Ok(UserRole::Admin),
+            "operator" => Ok(UserRole::Operator),
+            "technician" => Ok(UserRole::Technician),
+            "user" => Ok(UserRole::User),
+            "viewer" => Ok(UserRole::Viewer),
+            "guest" => Ok(UserRole::Guest),
+            other => Err(format!("Unknown role: {}", other)),
+        }
+    }
+}
+
+/// Per-agent access grant for a user
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Permission {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub agent_id: Uuid,
+    pub can_view: bool,
+    pub can_control: bool,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -51,10 +121,58 @@ pub struct Agent {
     pub last_seen: Option<DateTime<Utc>>,
     pub status: String,
     pub connection_info: sqlx::types::Json<HashMap<String, serde_json::Value>>,
-    pub capabilities: sqlx::types::Json<HashMap<String, serde_json::Value>>,
+    pub capabilities: sqlx::types::Json<AgentCapabilities>,
     pub settings: sqlx::types::Json<HashMap<String, serde_json::Value>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Group the device is filed under, if any
+    pub group_id: Option<Uuid>,
+    /// Lowercase labels, sorted and without duplicates
+    pub tags: Vec<String>,
+    /// Short name technicians reach the device by instead of its ID
+    pub alias: Option<String>,
+}
+
+impl Agent {
+    /// Network adapters from the agent's last registration
+    pub fn network_interfaces(&self) -> Vec<NetworkInterface> {
+        self.connection_info
+            .get("interfaces")
+            .and_then(|interfaces| serde_json::from_value(interfaces.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    /// Region from a `region:<name>` tag, used to pick a nearby relay node
+    pub fn region_hint(&self) -> Option<String> {
+        self.tags.iter().find_map(|tag| tag.strip_prefix("region:")).map(str::to_string)
+    }
+}
+
+/// A named set of devices, e.g. a site or a customer
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DeviceGroup {
+    pub id: Uuid,
+    pub organization_id: Option<Uuid>,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A network adapter an agent reports when it registers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    /// Hardware address, `aa:bb:cc:dd:ee:ff`; absent on adapters without one
+    #[serde(default)]
+    pub mac: Option<String>,
+    /// IPv4 addresses in CIDR notation, e.g. `192.168.1.20/24`
+    #[serde(default)]
+    pub ipv4: Vec<String>,
+    /// IPv6 addresses in CIDR notation, e.g. `2001:db8::20/64`; absent from
+    /// agents predating IPv6 support
+    #[serde(default)]
+    pub ipv6: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -72,6 +190,9 @@ pub struct Session {
     pub frames_captured: i32,
     pub settings: sqlx::types::Json<HashMap<String, serde_json::Value>>,
     pub metadata: sqlx::types::Json<HashMap<String, serde_json::Value>>,
+    /// Ticket in the organization's PSA the session belongs to
+    #[serde(default)]
+    pub ticket_ref: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -87,6 +208,105 @@ pub struct SessionAuditLog {
     pub agent_id: Option<Uuid>,
 }
 
+/// Entry in the server-wide audit trail of privileged actions
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AuditLog {
+    pub id: Uuid,
+    pub timestamp: DateTime<Utc>,
+    /// Subsystem that performed the action, such as "auth", "session",
+    /// "pam", "terminal", "registry", "toolbox" or "config"
+    pub category: String,
+    pub action: String,
+    pub actor: Option<String>,
+    pub session_id: Option<Uuid>,
+    /// Agent the action was taken on
+    #[serde(default)]
+    pub agent_id: Option<Uuid>,
+    /// Secrets are redacted before the entry is stored
+    pub details: sqlx::types::Json<serde_json::Value>,
+    /// Client address, as forwarded by the proxy when there is one
+    #[serde(default)]
+    pub source_ip: Option<String>,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+}
+
+impl AuditLog {
+    pub fn new(category: &str, action: &str) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            category: category.to_string(),
+            action: action.to_string(),
+            actor: None,
+            session_id: None,
+            agent_id: None,
+            details: sqlx::types::Json(serde_json::Value::Object(Default::default())),
+            source_ip: None,
+            user_agent: None,
+        }
+    }
+
+    pub fn actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    pub fn session(mut self, session_id: Uuid) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    pub fn agent(mut self, agent_id: Uuid) -> Self {
+        self.agent_id = Some(agent_id);
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.details = sqlx::types::Json(details);
+        self
+    }
+
+    /// Where the request that caused the action came from
+    pub fn request(mut self, context: &crate::audit::RequestContext) -> Self {
+        self.source_ip = context.source_ip.clone();
+        self.user_agent = context.user_agent.clone();
+        self
+    }
+}
+
+/// Result of a command a technician ran from a session window's Commands tab
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionCommand {
+    /// Assigned by the session window, so retried uploads are recognised
+    pub id: Uuid,
+    pub command: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    /// When the command started
+    pub execution_time: DateTime<Utc>,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub timeout_seconds: u32,
+    /// Output limit from `#maxlength`, in bytes
+    #[serde(default)]
+    pub max_length: usize,
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Email of the technician; set by the server from the caller's token
+    #[serde(default)]
+    pub executed_by: String,
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default)]
+    pub timed_out: bool,
+    #[serde(default)]
+    pub cancelled: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
 #[sqlx(type_name = "VARCHAR", rename_all = "lowercase")]
 pub enum SessionType {
@@ -111,6 +331,23 @@ impl std::fmt::Display for SessionType {
     }
 }
 
+impl std::str::FromStr for SessionType {
+    type Err = String;
+
+    /// Parse the name a session record keeps its type under
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "console" => Ok(SessionType::Console),
+            "backstage" => Ok(SessionType::Backstage),
+            "adhoc" => Ok(SessionType::Adhoc),
+            "file_transfer" => Ok(SessionType::FileTransfer),
+            "control" => Ok(SessionType::Control),
+            "view" => Ok(SessionType::View),
+            other => Err(format!("Unknown session type: {}", other)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
 #[sqlx(type_name = "VARCHAR", rename_all = "lowercase")]
 pub enum SessionStatus {
@@ -0,0 +1,518 @@
+//! Organizations and the tenant each request is scoped to
+//!
+//! Devices, sessions, groups, policies and tools belong to at most one
+//! organization. A user's token names the organization they work in, and
+//! users in several organizations switch between them for a new token.
+//! Admins in no organization are super-admins: they see every organization
+//! and are the only ones who manage them. Records of another organization are
+//! reported as not found rather than forbidden, so one tenant cannot learn
+//! what another has.
+
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::auth::{authz, jwt::{error, AuthError, AuthUser, JwtService, TokenResponse}};
+use crate::models::AuditLog;
+use crate::AppState;
+
+/// Longest organization name, in characters
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Shortest and longest slug, in characters
+const MIN_SLUG_LENGTH: usize = 2;
+const MAX_SLUG_LENGTH: usize = 100;
+
+/// Membership role given when a request names none
+const DEFAULT_MEMBER_ROLE: &str = "member";
+
+/// Records a request may see
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Tenant {
+    /// Those of every organization, for super-admins and the server itself
+    #[default]
+    All,
+    /// Those of one organization, or with `None` those of no organization
+    Organization(Option<Uuid>),
+}
+
+impl Tenant {
+    /// The tenant `user`'s token is for
+    pub fn of(user: &AuthUser) -> Result<Self, AuthError> {
+        match &user.org_id {
+            Some(org_id) => Uuid::parse_str(org_id)
+                .map(|org_id| Tenant::Organization(Some(org_id)))
+                .map_err(|_| AuthError::InvalidToken),
+            None if authz::user_role(user)?.is_admin() => Ok(Tenant::All),
+            None => Ok(Tenant::Organization(None)),
+        }
+    }
+
+    pub fn is_super_admin(&self) -> bool {
+        matches!(self, Tenant::All)
+    }
+
+    /// Whether records of `organization_id` are visible
+    pub fn includes(&self, organization_id: Option<Uuid>) -> bool {
+        match self {
+            Tenant::All => true,
+            Tenant::Organization(own) => *own == organization_id,
+        }
+    }
+
+    /// Organization records created in this tenant belong to
+    pub fn organization_id(&self) -> Option<Uuid> {
+        match self {
+            Tenant::All => None,
+            Tenant::Organization(organization_id) => *organization_id,
+        }
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for Tenant
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        Tenant::of(&user)
+    }
+}
+
+/// Not found response for a device outside the caller's tenant, worded like
+/// the one for a device that does not exist
+pub fn device_not_found(agent_id: Uuid) -> AuthError {
+    AuthError::NotFound(format!("Device not found: {}", agent_id))
+}
+
+/// Not found response for a session outside the caller's tenant
+pub fn session_not_found(session_id: Uuid) -> AuthError {
+    AuthError::NotFound(format!("Session not found: {}", session_id))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OrganizationError {
+    NotFound,
+    /// Another organization already has this slug
+    DuplicateSlug,
+    /// The organization still has devices
+    NotEmpty(usize),
+    /// Only admins outside any organization manage organizations
+    SuperAdminOnly,
+    /// Memberships are stored with the users, in the database
+    DatabaseRequired,
+    Database(String),
+    Invalid(String),
+}
+
+impl IntoResponse for OrganizationError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            OrganizationError::NotFound => (StatusCode::NOT_FOUND, "Organization not found".to_string()),
+            OrganizationError::DuplicateSlug => (StatusCode::CONFLICT, "An organization with this slug already exists".to_string()),
+            OrganizationError::NotEmpty(devices) => (
+                StatusCode::CONFLICT,
+                format!("The organization still has {} devices", devices),
+            ),
+            OrganizationError::SuperAdminOnly => (StatusCode::FORBIDDEN, "Only super-admins manage organizations".to_string()),
+            OrganizationError::DatabaseRequired => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Organization members are kept in the database, which is not configured".to_string(),
+            ),
+            OrganizationError::Database(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)),
+            OrganizationError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        error(status, message)
+    }
+}
+
+/// Trimmed organization name, or why it can't be used
+pub fn normalize_name(name: &str) -> Result<String, OrganizationError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(OrganizationError::Invalid("Organization name is empty".to_string()));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(OrganizationError::Invalid(format!(
+            "Organization name is longer than {} characters", MAX_NAME_LENGTH
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// Slug trimmed and lowercased, or why it can't be used
+pub fn normalize_slug(slug: &str) -> Result<String, OrganizationError> {
+    let slug = slug.trim().to_lowercase();
+    let valid = (MIN_SLUG_LENGTH..=MAX_SLUG_LENGTH).contains(&slug.len())
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-');
+    if !valid {
+        return Err(OrganizationError::Invalid(format!(
+            "Invalid slug '{}': slugs are {} to {} letters, digits and inner dashes",
+            slug, MIN_SLUG_LENGTH, MAX_SLUG_LENGTH
+        )));
+    }
+    Ok(slug)
+}
+
+/// Slug derived from an organization's name
+pub fn slug_from_name(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').chars().take(MAX_SLUG_LENGTH).collect()
+}
+
+// ============================================================================
+// API Handlers
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOrganizationRequest {
+    pub name: String,
+    /// Derived from the name when unset
+    #[serde(default)]
+    pub slug: Option<String>,
+    #[serde(default)]
+    pub settings: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateOrganizationRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub slug: Option<String>,
+    #[serde(default)]
+    pub settings: Option<HashMap<String, serde_json::Value>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MemberRequest {
+    /// Membership role, `member` when unset
+    #[serde(default)]
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SwitchOrganizationRequest {
+    /// Organization to work in; null takes super-admins back to all of them
+    pub organization_id: Option<Uuid>,
+}
+
+/// `id` as a UUID, or a 400 naming `what` it should have identified
+pub(crate) fn parse_id(id: &str, what: &str) -> Result<Uuid, OrganizationError> {
+    Uuid::parse_str(id).map_err(|_| OrganizationError::Invalid(format!("Invalid {} ID format", what)))
+}
+
+fn require_super_admin(tenant: &Tenant) -> Result<(), OrganizationError> {
+    if tenant.is_super_admin() {
+        Ok(())
+    } else {
+        Err(OrganizationError::SuperAdminOnly)
+    }
+}
+
+/// Organizations in `user`'s token
+fn token_organizations(user: &AuthUser) -> Vec<Uuid> {
+    user.org_id.iter()
+        .chain(user.orgs.iter())
+        .filter_map(|id| Uuid::parse_str(id).ok())
+        .collect()
+}
+
+/// Every organization with its device count for super-admins, the caller's
+/// own for everybody else
+pub async fn api_list_organizations(State(app_state): State<AppState>, user: AuthUser, tenant: Tenant) -> Response {
+    let mut organizations = app_state.device_manager.list_organizations().await;
+    if !tenant.is_super_admin() {
+        let own = token_organizations(&user);
+        organizations.retain(|(organization, _)| own.contains(&organization.id));
+    }
+    let organizations: Vec<_> = organizations
+        .into_iter()
+        .map(|(organization, device_count)| {
+            let mut organization = serde_json::json!(organization);
+            organization["device_count"] = device_count.into();
+            organization
+        })
+        .collect();
+    Json(serde_json::json!({ "organizations": organizations })).into_response()
+}
+
+pub async fn api_create_organization(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Json(request): Json<CreateOrganizationRequest>,
+) -> Response {
+    if let Err(e) = require_super_admin(&tenant) {
+        return e.into_response();
+    }
+    let slug = request.slug.unwrap_or_else(|| slug_from_name(&request.name));
+    let device_manager = &app_state.device_manager;
+    match device_manager.create_organization(&request.name, &slug, request.settings).await {
+        Ok(organization) => {
+            device_manager.record_audit(
+                AuditLog::new("config", "organization_created")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "organization_id": organization.id, "slug": organization.slug })),
+            ).await;
+            (StatusCode::CREATED, Json(organization)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// An organization the caller belongs to, or any for super-admins
+pub async fn api_get_organization(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(organization_id): Path<String>,
+) -> Response {
+    let organization_id = match parse_id(&organization_id, "organization") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    if !tenant.is_super_admin() && !token_organizations(&user).contains(&organization_id) {
+        return OrganizationError::NotFound.into_response();
+    }
+    match app_state.device_manager.get_organization(organization_id).await {
+        Some((organization, device_count)) => Json(serde_json::json!({
+            "organization": organization,
+            "device_count": device_count,
+        })).into_response(),
+        None => OrganizationError::NotFound.into_response(),
+    }
+}
+
+pub async fn api_update_organization(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(organization_id): Path<String>,
+    Json(request): Json<UpdateOrganizationRequest>,
+) -> Response {
+    if let Err(e) = require_super_admin(&tenant) {
+        return e.into_response();
+    }
+    let organization_id = match parse_id(&organization_id, "organization") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    let updated = device_manager.update_organization(
+        organization_id,
+        request.name.as_deref(),
+        request.slug.as_deref(),
+        request.settings,
+    ).await;
+    match updated {
+        Ok(organization) => {
+            device_manager.record_audit(
+                AuditLog::new("config", "organization_updated")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "organization_id": organization.id, "slug": organization.slug })),
+            ).await;
+            Json(organization).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Delete an organization without devices, along with its groups and policies
+pub async fn api_delete_organization(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(organization_id): Path<String>,
+) -> Response {
+    if let Err(e) = require_super_admin(&tenant) {
+        return e.into_response();
+    }
+    let organization_id = match parse_id(&organization_id, "organization") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.delete_organization(organization_id).await {
+        Ok(organization) => {
+            device_manager.record_audit(
+                AuditLog::new("config", "organization_deleted")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "organization_id": organization.id, "slug": organization.slug })),
+            ).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Add a user to an organization, or change their role in it
+pub async fn api_add_member(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path((organization_id, member_id)): Path<(String, String)>,
+    body: Option<Json<MemberRequest>>,
+) -> Response {
+    if let Err(e) = require_super_admin(&tenant) {
+        return e.into_response();
+    }
+    let (organization_id, member_id) = match (parse_id(&organization_id, "organization"), parse_id(&member_id, "user")) {
+        (Ok(organization_id), Ok(member_id)) => (organization_id, member_id),
+        (Err(e), _) | (_, Err(e)) => return e.into_response(),
+    };
+    let Some(db) = &app_state.db else {
+        return OrganizationError::DatabaseRequired.into_response();
+    };
+    let role = body.and_then(|Json(request)| request.role).unwrap_or_else(|| DEFAULT_MEMBER_ROLE.to_string());
+    match db.add_user_organization(member_id, organization_id, &role).await {
+        Ok(true) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("config", "organization_member_added")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "organization_id": organization_id, "user_id": member_id, "role": role })),
+            ).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        // The user or the organization does not exist
+        Ok(false) => OrganizationError::NotFound.into_response(),
+        Err(e) => OrganizationError::Database(e.to_string()).into_response(),
+    }
+}
+
+pub async fn api_remove_member(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path((organization_id, member_id)): Path<(String, String)>,
+) -> Response {
+    if let Err(e) = require_super_admin(&tenant) {
+        return e.into_response();
+    }
+    let (organization_id, member_id) = match (parse_id(&organization_id, "organization"), parse_id(&member_id, "user")) {
+        (Ok(organization_id), Ok(member_id)) => (organization_id, member_id),
+        (Err(e), _) | (_, Err(e)) => return e.into_response(),
+    };
+    let Some(db) = &app_state.db else {
+        return OrganizationError::DatabaseRequired.into_response();
+    };
+    match db.remove_user_organization(member_id, organization_id).await {
+        Ok(true) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("config", "organization_member_removed")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "organization_id": organization_id, "user_id": member_id })),
+            ).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => OrganizationError::NotFound.into_response(),
+        Err(e) => OrganizationError::Database(e.to_string()).into_response(),
+    }
+}
+
+/// Issue tokens for another organization the caller belongs to; super-admins
+/// may step into any organization and back out to all of them
+pub async fn api_switch_organization(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Json(request): Json<SwitchOrganizationRequest>,
+) -> Result<Json<TokenResponse>, AuthError> {
+    let db = app_state.db.as_ref().ok_or(AuthError::InvalidToken)?;
+    let memberships = db.get_user_organizations(user.user_id)
+        .await
+        .map_err(|_| AuthError::InvalidToken)?;
+    let super_admin = memberships.is_empty() && authz::user_role(&user)?.is_admin();
+
+    let allowed = match request.organization_id {
+        Some(organization_id) if memberships.contains(&organization_id) => true,
+        Some(organization_id) => super_admin && app_state.device_manager.get_organization(organization_id).await.is_some(),
+        // Users in no organization have only the one tenant
+        None => memberships.is_empty(),
+    };
+    if !allowed {
+        return Err(AuthError::NotFound(match request.organization_id {
+            Some(organization_id) => format!("Organization not found: {}", organization_id),
+            None => "Pick one of your organizations".to_string(),
+        }));
+    }
+
+    let orgs: Vec<String> = memberships.iter().map(Uuid::to_string).collect();
+    let org_id = request.organization_id.map(|id| id.to_string());
+    let tokens = JwtService::new(&app_state.config.jwt_secret)
+        .generate_token_pair(&user.user_id, &user.email, &user.role, org_id.as_deref(), &orgs)
+        .map_err(|_| AuthError::InvalidToken)?;
+    app_state.device_manager.record_audit(
+        AuditLog::new("auth", "organization_switched")
+            .actor(user.user_id.to_string())
+            .details(serde_json::json!({ "from": user.org_id, "to": request.organization_id })),
+    ).await;
+    Ok(Json(tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(role: &str, org_id: Option<Uuid>) -> AuthUser {
+        AuthUser {
+            user_id: Uuid::new_v4(),
+            email: "someone@example.com".to_string(),
+            role: role.to_string(),
+            org_id: org_id.map(|id| id.to_string()),
+            orgs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_tenant_of_token() {
+        let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let super_admin = Tenant::of(&user("admin", None)).unwrap();
+        assert!(super_admin.is_super_admin());
+        assert!(super_admin.includes(Some(acme)) && super_admin.includes(None));
+
+        // An admin of one organization is scoped to it like anyone else
+        let org_admin = Tenant::of(&user("admin", Some(acme))).unwrap();
+        assert_eq!(org_admin, Tenant::Organization(Some(acme)));
+        assert!(org_admin.includes(Some(acme)));
+        assert!(!org_admin.includes(Some(globex)) && !org_admin.includes(None));
+        assert_eq!(org_admin.organization_id(), Some(acme));
+
+        // Users in no organization see what belongs to none
+        let technician = Tenant::of(&user("technician", None)).unwrap();
+        assert!(technician.includes(None) && !technician.includes(Some(acme)));
+
+        let mut forged = user("technician", None);
+        forged.org_id = Some("not-a-uuid".to_string());
+        assert!(Tenant::of(&forged).is_err());
+    }
+
+    #[test]
+    fn test_slugs() {
+        assert_eq!(normalize_slug(" Acme-Corp ").unwrap(), "acme-corp");
+        assert!(normalize_slug("a").is_err());
+        assert!(normalize_slug("-acme").is_err());
+        assert!(normalize_slug("acme corp").is_err());
+        assert_eq!(slug_from_name("Acme Corp. (EU)"), "acme-corp-eu");
+        assert_eq!(normalize_name("  Acme  ").unwrap(), "Acme");
+        assert!(normalize_name(" ").is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Path, Query, State},
+    extract::{ws::Message, Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     Json,
@@ -12,7 +12,8 @@ use tokio::sync::RwLock;
 use uuid::Uuid;
 use tracing::{info, warn};
 
-use crate::AppState;
+use crate::audit::{self, AuditFilter, RequestContext};
+use crate::{models::AuditLog, webhooks::WebhookEvent, AppState};
 
 /// Privileged Access Management system for elevation requests and logging
 pub struct PamManager {
@@ -665,6 +666,62 @@ impl PamManager {
         }
     }
     
+    /// Find an approved, unexpired elevation covering a remote session
+    pub async fn approved_elevation_for_session(&self, session_id: Uuid) -> Option<Uuid> {
+        let now = chrono::Utc::now();
+        let requests = self.elevation_requests.read().await;
+        requests.values()
+            .find(|req| {
+                req.session_id == session_id
+                    && matches!(req.status, ElevationStatus::Approved | ElevationStatus::Active)
+                    && req.expires_at > now
+            })
+            .map(|req| req.id)
+    }
+    
+    /// Get an elevation request by id
+    pub async fn get_elevation_request(&self, request_id: Uuid) -> Option<ElevationRequest> {
+        self.elevation_requests.read().await.get(&request_id).cloned()
+    }
+    
+    /// Record the agent's answer to an elevation it was asked to apply.
+    ///
+    /// A failed elevation marks the session's active request failed, so it is
+    /// no longer treated as in effect. Returns the request's id.
+    pub async fn record_agent_elevation(
+        &self,
+        session_id: Uuid,
+        success: bool,
+        details: serde_json::Value,
+    ) -> Option<Uuid> {
+        let (request_id, user_id) = {
+            let mut requests = self.elevation_requests.write().await;
+            let request = requests.values_mut()
+                .find(|req| req.session_id == session_id && req.status == ElevationStatus::Active)?;
+            if !success {
+                request.status = ElevationStatus::Failed;
+                request.denied_reason = details.get("error").and_then(|v| v.as_str()).map(str::to_string);
+            }
+            (request.id, request.user_id.clone())
+        };
+        
+        let action = if success { "agent_elevation_granted" } else { "agent_elevation_failed" };
+        self.create_audit_entry(request_id, session_id, &user_id, action, details).await;
+        Some(request_id)
+    }
+    
+    /// Record a privileged action performed under an elevation in the audit log
+    pub async fn record_elevated_action(
+        &self,
+        elevation_request_id: Uuid,
+        session_id: Uuid,
+        user_id: &str,
+        action: &str,
+        details: serde_json::Value,
+    ) {
+        self.create_audit_entry(elevation_request_id, session_id, user_id, action, details).await;
+    }
+    
     /// Get elevation requests for user
     pub async fn get_user_elevation_requests(&self, user_id: &str) -> Vec<ElevationRequest> {
         let requests = self.elevation_requests.read().await;
@@ -743,10 +800,43 @@ pub struct ExecuteCommandRequest {
 pub async fn api_request_elevation(
     State(app_state): State<AppState>,
     Path(session_id): Path<Uuid>,
+    context: RequestContext,
     Json(request): Json<CreateElevationRequest>,
 ) -> Response {
     match app_state.device_manager.pam_manager.request_elevation(session_id, request).await {
-        Ok(elevation_request) => Json(elevation_request).into_response(),
+        Ok(elevation_request) => {
+            let mut entry = AuditLog::new("pam", "elevation_requested")
+                .actor(elevation_request.requested_by.clone())
+                .session(session_id)
+                .request(&context)
+                .details(serde_json::json!({
+                    "request_id": elevation_request.id,
+                    "user_id": elevation_request.user_id,
+                    "elevation_type": elevation_request.elevation_type,
+                    "reason": elevation_request.reason,
+                    "auto_approved": elevation_request.auto_approved,
+                }));
+            let session = app_state.device_manager.get_session(session_id).await;
+            if let Some(session) = &session {
+                entry = entry.agent(session.agent_id);
+            }
+            app_state.device_manager.record_audit(entry).await;
+            app_state.device_manager.webhooks.notify(
+                WebhookEvent::PamElevationRequested,
+                session.as_ref().and_then(|session| session.organization_id),
+                serde_json::json!({
+                    "request_id": elevation_request.id,
+                    "session_id": session_id,
+                    "device_id": session.as_ref().map(|session| session.agent_id),
+                    "user_id": elevation_request.user_id,
+                    "requested_by": elevation_request.requested_by,
+                    "elevation_type": elevation_request.elevation_type,
+                    "reason": elevation_request.reason,
+                    "auto_approved": elevation_request.auto_approved,
+                }),
+            ).await;
+            Json(elevation_request).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -760,6 +850,7 @@ pub async fn api_request_elevation(
 pub async fn api_approve_elevation(
     State(app_state): State<AppState>,
     Path(request_id): Path<Uuid>,
+    context: RequestContext,
     Json(request): Json<serde_json::Value>,
 ) -> Response {
     let approver_id = match request.get("approver_id").and_then(|v| v.as_str()) {
@@ -772,10 +863,18 @@ pub async fn api_approve_elevation(
         ).into_response(),
     };
     
-    match app_state.device_manager.pam_manager.approve_elevation(request_id, approver_id).await {
-        Ok(_) => Json(serde_json::json!({
-            "status": "approved"
-        })).into_response(),
+    match app_state.device_manager.pam_manager.approve_elevation(request_id, approver_id.clone()).await {
+        Ok(_) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("pam", "elevation_approved")
+                    .actor(approver_id)
+                    .request(&context)
+                    .details(serde_json::json!({ "request_id": request_id })),
+            ).await;
+            Json(serde_json::json!({
+                "status": "approved"
+            })).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -789,9 +888,48 @@ pub async fn api_approve_elevation(
 pub async fn api_start_elevated_session(
     State(app_state): State<AppState>,
     Path(request_id): Path<Uuid>,
+    context: RequestContext,
 ) -> Response {
     match app_state.device_manager.pam_manager.start_elevated_session(request_id).await {
-        Ok(session) => Json(session).into_response(),
+        Ok(session) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("pam", "elevated_session_started")
+                    .actor(session.user_id.clone())
+                    .session(session.session_id)
+                    .request(&context)
+                    .details(serde_json::json!({
+                        "request_id": request_id,
+                        "elevated_user": session.elevated_user,
+                        "expires_at": session.expires_at,
+                    })),
+            ).await;
+            
+            // The agent behind the remote session obtains the rights and
+            // answers with an ElevationResult
+            if let Some(request) = app_state.device_manager.pam_manager.get_elevation_request(request_id).await {
+                let message = serde_json::json!({
+                    "type": "ElevationRequest",
+                    "session_id": request.session_id.to_string(),
+                    "level": request.elevation_type,
+                });
+                if let Err(e) = app_state.device_manager
+                    .send_to_session_agent(request.session_id, Message::Text(message.to_string()))
+                    .await
+                {
+                    warn!("Elevation {} could not be delivered to the agent: {}", request_id, e);
+                    app_state.device_manager.pam_manager
+                        .record_agent_elevation(request.session_id, false, serde_json::json!({ "error": e }))
+                        .await;
+                    return (
+                        StatusCode::BAD_GATEWAY,
+                        Json(serde_json::json!({
+                            "error": format!("Elevation could not be delivered to the agent: {}", e)
+                        }))
+                    ).into_response();
+                }
+            }
+            Json(session).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -805,6 +943,7 @@ pub async fn api_start_elevated_session(
 pub async fn api_execute_elevated_command(
     State(app_state): State<AppState>,
     Path(session_id): Path<Uuid>,
+    context: RequestContext,
     Json(request): Json<ExecuteCommandRequest>,
 ) -> Response {
     match app_state.device_manager.pam_manager.execute_elevated_command(
@@ -812,7 +951,20 @@ pub async fn api_execute_elevated_command(
         request.command,
         request.working_directory,
     ).await {
-        Ok(execution) => Json(execution).into_response(),
+        Ok(execution) => {
+            app_state.device_manager.record_audit(
+                AuditLog::new("pam", "elevated_command_executed")
+                    .session(session_id)
+                    .request(&context)
+                    .details(serde_json::json!({
+                        "command": execution.command,
+                        "working_directory": execution.working_directory,
+                        "exit_code": execution.exit_code,
+                        "duration_ms": execution.duration_ms,
+                    })),
+            ).await;
+            Json(execution).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -822,16 +974,26 @@ pub async fn api_execute_elevated_command(
     }
 }
 
-/// Get PAM audit log
+/// Get PAM audit log, newest first, from the server-wide audit trail
 pub async fn api_get_pam_audit_log(
     State(app_state): State<AppState>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Response {
     let limit = params.get("limit")
-        .and_then(|l| l.parse::<usize>().ok());
-    
-    let audit_log = app_state.device_manager.pam_manager.get_audit_log(limit).await;
-    Json(audit_log).into_response()
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(audit::MAX_AUDIT_PAGE)
+        .min(audit::MAX_AUDIT_PAGE);
+    let filter = AuditFilter { category: Some("pam".to_string()), ..Default::default() };
+
+    match app_state.device_manager.query_audit(&filter, limit, 0).await {
+        Ok((entries, _)) => Json(entries).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": format!("Failed to read the audit log: {}", e)
+            }))
+        ).into_response(),
+    }
 }
 
 /// Get PAM statistics
@@ -840,4 +1002,49 @@ pub async fn api_get_pam_stats(
 ) -> impl IntoResponse {
     let stats = app_state.device_manager.pam_manager.get_pam_stats().await;
     Json(stats)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An approved and started RunAsAdmin elevation for `session_id`
+    async fn active_elevation(pam: &PamManager, session_id: Uuid) -> Uuid {
+        let request = pam.request_elevation(session_id, CreateElevationRequest {
+            user_id: "tech".to_string(),
+            user_domain: None,
+            requested_by: "tech".to_string(),
+            reason: "Install updates".to_string(),
+            target_process: None,
+            target_command: None,
+            elevation_type: ElevationType::RunAsAdmin,
+        }).await.unwrap();
+        pam.approve_elevation(request.id, "lead".to_string()).await.unwrap();
+        pam.start_elevated_session(request.id).await.unwrap();
+        request.id
+    }
+
+    #[tokio::test]
+    async fn test_agent_elevation_failure_ends_the_elevation() {
+        let pam = PamManager::new();
+        let session_id = Uuid::new_v4();
+        let request_id = active_elevation(&pam, session_id).await;
+
+        let granted = serde_json::json!({ "success": true, "method": { "method": "inherited" } });
+        assert_eq!(pam.record_agent_elevation(session_id, true, granted).await, Some(request_id));
+        assert_eq!(pam.get_elevation_request(request_id).await.unwrap().status, ElevationStatus::Active);
+
+        let denied = serde_json::json!({ "success": false, "denied": true, "error": "Elevation denied: the UAC prompt was declined" });
+        assert_eq!(pam.record_agent_elevation(session_id, false, denied).await, Some(request_id));
+        let request = pam.get_elevation_request(request_id).await.unwrap();
+        assert_eq!(request.status, ElevationStatus::Failed);
+        assert_eq!(request.denied_reason.as_deref(), Some("Elevation denied: the UAC prompt was declined"));
+        assert!(pam.approved_elevation_for_session(session_id).await.is_none());
+
+        // Nothing is active for the session any more
+        assert_eq!(pam.record_agent_elevation(session_id, true, serde_json::json!({})).await, None);
+
+        let actions: Vec<_> = pam.get_audit_log(None).await.into_iter().map(|entry| entry.action).collect();
+        assert!(actions.contains(&"agent_elevation_granted".to_string()));
+        assert!(actions.contains(&"agent_elevation_failed".to_string()));
+    }
+}
@@ -0,0 +1,748 @@
+//! Client policies pushed to agents
+//!
+//! Admins write policy documents for an organization, a device group or a
+//! single device. An agent's effective policy lays its group's document over
+//! its organization's and its own over both, so each setting comes from the
+//! most specific document that makes it. Settings no document makes are left
+//! to the agent's local config. The relay pushes the effective policy to an
+//! agent as a `PolicyUpdate` when it registers and whenever a document that
+//! applies to it changes.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::approvals;
+use crate::auth::jwt::{error, AuthUser};
+use crate::models::{Agent, AuditLog};
+use crate::organizations::{self, Tenant};
+use crate::file_drop::MAX_DROP_FILE_SIZE;
+use crate::relay::bandwidth::BANDWIDTH_RANGE;
+use crate::AppState;
+
+/// Highest frame rate cap a policy may set
+pub const MAX_FPS_LIMIT: u32 = 120;
+
+/// Heartbeat intervals a policy may set, in seconds
+const HEARTBEAT_RANGE: std::ops::RangeInclusive<u64> = 5..=3600;
+
+/// Missed heartbeats a policy may let pass before a device is offline
+const MISSED_HEARTBEATS_RANGE: std::ops::RangeInclusive<u32> = 2..=20;
+
+/// Session types a policy may allow, as the relay names them
+pub const SESSION_TYPES: &[&str] = &["console", "backstage", "adhoc", "file_transfer", "control", "view"];
+
+/// Toolbox categories a policy may allow, as the agent names them
+pub const TOOL_CATEGORIES: &[&str] = &["system", "network", "security", "monitoring", "development", "custom"];
+
+/// Consent countdowns a policy may set, in seconds
+const CONSENT_TIMEOUT_RANGE: std::ops::RangeInclusive<u64> = 5..=600;
+
+/// How long a held backstage session may wait for approval, in seconds
+const APPROVAL_TTL_RANGE: std::ops::RangeInclusive<u64> = 60..=86400;
+
+/// CPU budgets a policy may set, in percent of one core
+const CPU_BUDGET_RANGE: std::ops::RangeInclusive<u32> = 5..=100;
+
+/// Memory ceilings a policy may set for capture and recording, in MB
+const MEMORY_LIMIT_RANGE: std::ops::RangeInclusive<u64> = 64..=16384;
+
+/// Pauses of remote access the end user may start, in hours
+const USER_PAUSE_RANGE: std::ops::RangeInclusive<u32> = 1..=168;
+
+/// What the agent does when nobody answers the consent prompt in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentTimeoutAction {
+    Accept,
+    Decline,
+}
+
+/// Which sessions the end user may end from the agent's tray
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserDisconnect {
+    All,
+    /// All but backstage sessions
+    Attended,
+    None,
+}
+
+/// Whether a session waits for the person at the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consent {
+    NotRequired,
+    Required,
+    /// Consent is required, but this unattended session is let through
+    Bypassed,
+}
+
+/// What a policy document applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "VARCHAR", rename_all = "snake_case")]
+pub enum PolicyScope {
+    Organization,
+    Group,
+    Device,
+}
+
+impl PolicyScope {
+    /// Broadest first, the order documents are laid over each other in
+    pub fn rank(self) -> u8 {
+        match self {
+            PolicyScope::Organization => 0,
+            PolicyScope::Group => 1,
+            PolicyScope::Device => 2,
+        }
+    }
+}
+
+/// Settings a policy makes; unset ones are left to broader policies, or to
+/// the agent
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyDocument {
+    /// Frame rate screen capture may not exceed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fps: Option<u32>,
+    /// Bandwidth no session may exceed, in kbit/s, whatever cap it asks for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth_kbps: Option<u32>,
+    /// Seconds between agent heartbeats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Missed heartbeats after which the relay takes the device offline
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_after_missed_heartbeats: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clipboard_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_transfer_enabled: Option<bool>,
+    /// Whether technicians may drop or paste files onto the device's screen
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_drop_enabled: Option<bool>,
+    /// Largest file a drop may carry, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_drop_bytes: Option<u64>,
+    /// Whether technicians may run toolbox tools on the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toolbox_enabled: Option<bool>,
+    /// Seconds without technician input before the agent ends a session;
+    /// 0 never ends it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Record every session, whatever the session request asks for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_required: Option<bool>,
+    /// Session types the device accepts; unset allows all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_session_types: Option<Vec<String>>,
+    /// The person at the device approves each session before it starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_required: Option<bool>,
+    /// Seconds the consent prompt waits for an answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_timeout_secs: Option<u64>,
+    /// What an unanswered consent prompt means; declines when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_timeout_action: Option<ConsentTimeoutAction>,
+    /// Backstage sessions skip the consent prompt; they do when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backstage_consent_bypass: Option<bool>,
+    /// Backstage sessions wait until someone other than the requesting
+    /// technician approves them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backstage_requires_approval: Option<bool>,
+    /// Seconds a backstage session waits for approval before it expires
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backstage_approval_ttl_secs: Option<u64>,
+    /// SHA-256 of the tool executables the device may run; unset allows any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_tool_checksums: Option<Vec<String>>,
+    /// Toolbox categories the device may run; unset allows all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_tool_categories: Option<Vec<String>>,
+    /// Whether the agent reports its hardware and software inventory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inventory_enabled: Option<bool>,
+    /// Show the end user an indicator whenever a technician's input reaches
+    /// the device, backstage sessions included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_indicator_required: Option<bool>,
+    /// Whether the agent sends its crash reports to the server; the end
+    /// user's local setting decides when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crash_reports_enabled: Option<bool>,
+    /// Technicians write a close-out note before they end a session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closeout_note_required: Option<bool>,
+    /// Share of one CPU core screen capture and encoding may use, in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_budget_percent: Option<u32>,
+    /// Memory the agent's frame and recording buffers may hold, in MB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<u64>,
+    /// Run the agent below normal CPU and I/O priority, so the person at
+    /// the device keeps the machine's attention
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_priority: Option<bool>,
+    /// Which sessions the end user may end from the tray; all when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_disconnect: Option<UserDisconnect>,
+    /// Whether the end user may pause remote access from the tray
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_pause_enabled: Option<bool>,
+    /// Longest pause the end user may start, in hours
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_user_pause_hours: Option<u32>,
+}
+
+impl PolicyDocument {
+    /// Check the settings are within range, normalizing session type names
+    pub fn validate(mut self) -> Result<Self, PolicyError> {
+        if let Some(fps) = self.max_fps {
+            if !(1..=MAX_FPS_LIMIT).contains(&fps) {
+                return Err(PolicyError::Invalid(format!("max_fps must be between 1 and {}", MAX_FPS_LIMIT)));
+            }
+        }
+        if let Some(kbps) = self.max_bandwidth_kbps {
+            if !BANDWIDTH_RANGE.contains(&kbps) {
+                return Err(PolicyError::Invalid(format!(
+                    "max_bandwidth_kbps must be between {} and {}",
+                    BANDWIDTH_RANGE.start(),
+                    BANDWIDTH_RANGE.end()
+                )));
+            }
+        }
+        if let Some(secs) = self.heartbeat_interval_secs {
+            if !HEARTBEAT_RANGE.contains(&secs) {
+                return Err(PolicyError::Invalid(format!(
+                    "heartbeat_interval_secs must be between {} and {}",
+                    HEARTBEAT_RANGE.start(),
+                    HEARTBEAT_RANGE.end()
+                )));
+            }
+        }
+        if let Some(missed) = self.offline_after_missed_heartbeats {
+            if !MISSED_HEARTBEATS_RANGE.contains(&missed) {
+                return Err(PolicyError::Invalid(format!(
+                    "offline_after_missed_heartbeats must be between {} and {}",
+                    MISSED_HEARTBEATS_RANGE.start(),
+                    MISSED_HEARTBEATS_RANGE.end()
+                )));
+            }
+        }
+        if let Some(bytes) = self.max_file_drop_bytes {
+            if !(1..=MAX_DROP_FILE_SIZE).contains(&bytes) {
+                return Err(PolicyError::Invalid(format!("max_file_drop_bytes must be between 1 and {}", MAX_DROP_FILE_SIZE)));
+            }
+        }
+        if let Some(secs) = self.consent_timeout_secs {
+            if !CONSENT_TIMEOUT_RANGE.contains(&secs) {
+                return Err(PolicyError::Invalid(format!(
+                    "consent_timeout_secs must be between {} and {}",
+                    CONSENT_TIMEOUT_RANGE.start(),
+                    CONSENT_TIMEOUT_RANGE.end()
+                )));
+            }
+        }
+        if let Some(secs) = self.backstage_approval_ttl_secs {
+            if !APPROVAL_TTL_RANGE.contains(&secs) {
+                return Err(PolicyError::Invalid(format!(
+                    "backstage_approval_ttl_secs must be between {} and {}",
+                    APPROVAL_TTL_RANGE.start(),
+                    APPROVAL_TTL_RANGE.end()
+                )));
+            }
+        }
+        if let Some(percent) = self.cpu_budget_percent {
+            if !CPU_BUDGET_RANGE.contains(&percent) {
+                return Err(PolicyError::Invalid(format!(
+                    "cpu_budget_percent must be between {} and {}",
+                    CPU_BUDGET_RANGE.start(),
+                    CPU_BUDGET_RANGE.end()
+                )));
+            }
+        }
+        if let Some(mb) = self.memory_limit_mb {
+            if !MEMORY_LIMIT_RANGE.contains(&mb) {
+                return Err(PolicyError::Invalid(format!(
+                    "memory_limit_mb must be between {} and {}",
+                    MEMORY_LIMIT_RANGE.start(),
+                    MEMORY_LIMIT_RANGE.end()
+                )));
+            }
+        }
+        if let Some(hours) = self.max_user_pause_hours {
+            if !USER_PAUSE_RANGE.contains(&hours) {
+                return Err(PolicyError::Invalid(format!(
+                    "max_user_pause_hours must be between {} and {}",
+                    USER_PAUSE_RANGE.start(),
+                    USER_PAUSE_RANGE.end()
+                )));
+            }
+        }
+        if let Some(types) = &mut self.allowed_session_types {
+            for session_type in types.iter_mut() {
+                *session_type = session_type.trim().to_lowercase();
+                if !SESSION_TYPES.contains(&session_type.as_str()) {
+                    return Err(PolicyError::Invalid(format!(
+                        "Unknown session type '{}'; expected one of {}",
+                        session_type,
+                        SESSION_TYPES.join(", ")
+                    )));
+                }
+            }
+            types.sort();
+            types.dedup();
+        }
+        if let Some(checksums) = &mut self.allowed_tool_checksums {
+            for checksum in checksums.iter_mut() {
+                *checksum = checksum.trim().to_lowercase();
+                if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(PolicyError::Invalid(format!("'{}' is not a hex SHA-256 checksum", checksum)));
+                }
+            }
+            checksums.sort();
+            checksums.dedup();
+        }
+        if let Some(categories) = &mut self.allowed_tool_categories {
+            for category in categories.iter_mut() {
+                *category = category.trim().to_lowercase();
+                if !TOOL_CATEGORIES.contains(&category.as_str()) {
+                    return Err(PolicyError::Invalid(format!(
+                        "Unknown tool category '{}'; expected one of {}",
+                        category,
+                        TOOL_CATEGORIES.join(", ")
+                    )));
+                }
+            }
+            categories.sort();
+            categories.dedup();
+        }
+        Ok(self)
+    }
+
+    /// `self` with every setting `over` makes replacing its own
+    pub fn overlay(&self, over: &PolicyDocument) -> PolicyDocument {
+        PolicyDocument {
+            max_fps: over.max_fps.or(self.max_fps),
+            max_bandwidth_kbps: over.max_bandwidth_kbps.or(self.max_bandwidth_kbps),
+            heartbeat_interval_secs: over.heartbeat_interval_secs.or(self.heartbeat_interval_secs),
+            offline_after_missed_heartbeats: over.offline_after_missed_heartbeats.or(self.offline_after_missed_heartbeats),
+            clipboard_enabled: over.clipboard_enabled.or(self.clipboard_enabled),
+            file_transfer_enabled: over.file_transfer_enabled.or(self.file_transfer_enabled),
+            file_drop_enabled: over.file_drop_enabled.or(self.file_drop_enabled),
+            max_file_drop_bytes: over.max_file_drop_bytes.or(self.max_file_drop_bytes),
+            toolbox_enabled: over.toolbox_enabled.or(self.toolbox_enabled),
+            idle_timeout_secs: over.idle_timeout_secs.or(self.idle_timeout_secs),
+            recording_required: over.recording_required.or(self.recording_required),
+            allowed_session_types: over.allowed_session_types.clone().or_else(|| self.allowed_session_types.clone()),
+            consent_required: over.consent_required.or(self.consent_required),
+            consent_timeout_secs: over.consent_timeout_secs.or(self.consent_timeout_secs),
+            consent_timeout_action: over.consent_timeout_action.or(self.consent_timeout_action),
+            backstage_consent_bypass: over.backstage_consent_bypass.or(self.backstage_consent_bypass),
+            backstage_requires_approval: over.backstage_requires_approval.or(self.backstage_requires_approval),
+            backstage_approval_ttl_secs: over.backstage_approval_ttl_secs.or(self.backstage_approval_ttl_secs),
+            allowed_tool_checksums: over.allowed_tool_checksums.clone().or_else(|| self.allowed_tool_checksums.clone()),
+            allowed_tool_categories: over.allowed_tool_categories.clone().or_else(|| self.allowed_tool_categories.clone()),
+            inventory_enabled: over.inventory_enabled.or(self.inventory_enabled),
+            input_indicator_required: over.input_indicator_required.or(self.input_indicator_required),
+            crash_reports_enabled: over.crash_reports_enabled.or(self.crash_reports_enabled),
+            closeout_note_required: over.closeout_note_required.or(self.closeout_note_required),
+            cpu_budget_percent: over.cpu_budget_percent.or(self.cpu_budget_percent),
+            memory_limit_mb: over.memory_limit_mb.or(self.memory_limit_mb),
+            background_priority: over.background_priority.or(self.background_priority),
+            user_disconnect: over.user_disconnect.or(self.user_disconnect),
+            user_pause_enabled: over.user_pause_enabled.or(self.user_pause_enabled),
+            max_user_pause_hours: over.max_user_pause_hours.or(self.max_user_pause_hours),
+        }
+    }
+
+    /// Whether a session of `session_type` waits for the person at the device
+    pub fn consent_for(&self, session_type: &str) -> Consent {
+        if self.consent_required != Some(true) {
+            return Consent::NotRequired;
+        }
+        if session_type.eq_ignore_ascii_case("backstage") && self.backstage_consent_bypass != Some(false) {
+            return Consent::Bypassed;
+        }
+        Consent::Required
+    }
+
+    /// Seconds a backstage session waits for approval, or `None` when it
+    /// starts without one
+    pub fn backstage_approval_ttl(&self) -> Option<u64> {
+        (self.backstage_requires_approval == Some(true))
+            .then(|| self.backstage_approval_ttl_secs.unwrap_or(approvals::DEFAULT_TTL_SECS))
+    }
+
+    /// Whether sessions of `session_type` may be started
+    pub fn allows_session(&self, session_type: &str) -> bool {
+        self.allowed_session_types
+            .as_ref()
+            .is_none_or(|types| types.iter().any(|allowed| allowed.eq_ignore_ascii_case(session_type)))
+    }
+}
+
+/// A stored policy document and what it applies to
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Policy {
+    pub id: Uuid,
+    pub scope: PolicyScope,
+    /// Organization, group or device; unset for agents in no organization
+    pub target_id: Option<Uuid>,
+    /// Organization of the target, which the policy is listed under
+    pub organization_id: Option<Uuid>,
+    pub document: sqlx::types::Json<PolicyDocument>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Policy {
+    /// Whether this policy applies to `agent`
+    pub fn applies_to(&self, agent: &Agent) -> bool {
+        match self.scope {
+            PolicyScope::Organization => self.target_id == agent.organization_id,
+            PolicyScope::Group => self.target_id.is_some() && self.target_id == agent.group_id,
+            PolicyScope::Device => self.target_id == Some(agent.id),
+        }
+    }
+}
+
+/// Effective policy of `agent` under `policies`, and the policies it came
+/// from, broadest first
+pub fn effective_policy<'a>(
+    policies: impl IntoIterator<Item = &'a Policy>,
+    agent: &Agent,
+) -> (PolicyDocument, Vec<Uuid>) {
+    let mut applicable: Vec<&Policy> = policies.into_iter().filter(|policy| policy.applies_to(agent)).collect();
+    applicable.sort_by_key(|policy| policy.scope.rank());
+    let document = applicable
+        .iter()
+        .fold(PolicyDocument::default(), |merged, policy| merged.overlay(&policy.document));
+    (document, applicable.iter().map(|policy| policy.id).collect())
+}
+
+/// `PolicyUpdate` frame carrying an agent's effective policy
+pub fn policy_update(policy: &PolicyDocument) -> serde_json::Value {
+    serde_json::json!({
+        "type": "PolicyUpdate",
+        "policy": policy,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolicyError {
+    NotFound,
+    /// The scope's target does not exist
+    TargetNotFound,
+    /// The target already has a policy
+    Duplicate,
+    Invalid(String),
+}
+
+impl IntoResponse for PolicyError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            PolicyError::NotFound => (StatusCode::NOT_FOUND, "Policy not found".to_string()),
+            PolicyError::TargetNotFound => (StatusCode::NOT_FOUND, "Policy target not found".to_string()),
+            PolicyError::Duplicate => (StatusCode::CONFLICT, "The target already has a policy".to_string()),
+            PolicyError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        error(status, message)
+    }
+}
+
+// ============================================================================
+// API Handlers
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePolicyRequest {
+    pub scope: PolicyScope,
+    /// Organization, group or device ID; unset with the organization scope
+    /// targets agents in no organization
+    #[serde(default)]
+    pub target_id: Option<Uuid>,
+    pub document: PolicyDocument,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatePolicyRequest {
+    pub document: PolicyDocument,
+}
+
+/// Policies of the caller's organization, broadest scope first
+pub async fn api_list_policies(State(app_state): State<AppState>, tenant: Tenant) -> Response {
+    let policies = app_state.device_manager.list_policies(tenant).await;
+    Json(serde_json::json!({ "policies": policies })).into_response()
+}
+
+pub async fn api_create_policy(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Json(request): Json<CreatePolicyRequest>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    match device_manager.create_policy(tenant, request.scope, request.target_id, request.document).await {
+        Ok(policy) => {
+            device_manager.record_audit(
+                AuditLog::new("config", "policy_created")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({
+                        "policy_id": policy.id,
+                        "scope": policy.scope,
+                        "target_id": policy.target_id,
+                        "document": policy.document,
+                    })),
+            ).await;
+            (StatusCode::CREATED, Json(policy)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn api_get_policy(State(app_state): State<AppState>, tenant: Tenant, Path(policy_id): Path<String>) -> Response {
+    let policy_id = match organizations::parse_id(&policy_id, "policy") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    match app_state.device_manager.get_policy(tenant, policy_id).await {
+        Some(policy) => Json(policy).into_response(),
+        None => PolicyError::NotFound.into_response(),
+    }
+}
+
+/// Replace a policy's document
+pub async fn api_update_policy(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(policy_id): Path<String>,
+    Json(request): Json<UpdatePolicyRequest>,
+) -> Response {
+    let policy_id = match organizations::parse_id(&policy_id, "policy") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.update_policy(tenant, policy_id, request.document).await {
+        Ok(policy) => {
+            device_manager.record_audit(
+                AuditLog::new("config", "policy_updated")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "policy_id": policy.id, "document": policy.document })),
+            ).await;
+            Json(policy).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn api_delete_policy(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(policy_id): Path<String>,
+) -> Response {
+    let policy_id = match organizations::parse_id(&policy_id, "policy") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.delete_policy(tenant, policy_id).await {
+        Ok(policy) => {
+            device_manager.record_audit(
+                AuditLog::new("config", "policy_deleted")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "policy_id": policy.id, "scope": policy.scope, "target_id": policy.target_id })),
+            ).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The policy a device runs under and the policies it is merged from
+pub async fn api_device_policy(State(app_state): State<AppState>, tenant: Tenant, Path(agent_id): Path<String>) -> Response {
+    let agent_id = match organizations::parse_id(&agent_id, "agent") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let in_tenant = app_state.device_manager.get_agent(agent_id).await
+        .is_some_and(|(agent, _)| tenant.includes(agent.organization_id));
+    if !in_tenant {
+        return error(StatusCode::NOT_FOUND, "Device not found");
+    }
+    match app_state.device_manager.effective_policy(agent_id).await {
+        Some((policy, sources)) => Json(serde_json::json!({
+            "device_id": agent_id,
+            "policy": policy,
+            "sources": sources,
+        }))
+        .into_response(),
+        None => error(StatusCode::NOT_FOUND, "Device not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixtures;
+
+    fn policy(scope: PolicyScope, target_id: Option<Uuid>, document: PolicyDocument) -> Policy {
+        Policy {
+            id: Uuid::new_v4(),
+            scope,
+            target_id,
+            organization_id: None,
+            document: sqlx::types::Json(document),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_device_beats_group_beats_organization() {
+        let (org, group) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut agent = fixtures::agent("kiosk-01");
+        agent.organization_id = Some(org);
+        agent.group_id = Some(group);
+
+        let policies = [
+            policy(PolicyScope::Device, Some(agent.id), PolicyDocument {
+                max_fps: Some(10),
+                ..Default::default()
+            }),
+            policy(PolicyScope::Organization, Some(org), PolicyDocument {
+                max_fps: Some(30),
+                max_bandwidth_kbps: Some(1000),
+                clipboard_enabled: Some(false),
+                heartbeat_interval_secs: Some(60),
+                allowed_session_types: Some(vec!["console".to_string()]),
+                ..Default::default()
+            }),
+            policy(PolicyScope::Group, Some(group), PolicyDocument {
+                max_fps: Some(15),
+                clipboard_enabled: Some(true),
+                recording_required: Some(true),
+                ..Default::default()
+            }),
+            // Another group's and the ungrouped organization's policies stay out
+            policy(PolicyScope::Group, Some(Uuid::new_v4()), PolicyDocument {
+                file_transfer_enabled: Some(false),
+                ..Default::default()
+            }),
+            policy(PolicyScope::Organization, None, PolicyDocument {
+                toolbox_enabled: Some(false),
+                ..Default::default()
+            }),
+        ];
+
+        let (effective, sources) = effective_policy(&policies, &agent);
+        assert_eq!(effective, PolicyDocument {
+            max_fps: Some(10),
+            max_bandwidth_kbps: Some(1000),
+            heartbeat_interval_secs: Some(60),
+            clipboard_enabled: Some(true),
+            recording_required: Some(true),
+            allowed_session_types: Some(vec!["console".to_string()]),
+            ..Default::default()
+        });
+        assert_eq!(sources, [policies[1].id, policies[2].id, policies[0].id]);
+        assert!(effective.allows_session("Console"));
+        assert!(!effective.allows_session("backstage"));
+
+        // Leaving the group drops its settings
+        agent.group_id = None;
+        let (effective, _) = effective_policy(&policies, &agent);
+        assert_eq!(effective.clipboard_enabled, Some(false));
+        assert_eq!(effective.recording_required, None);
+        assert_eq!(effective.max_fps, Some(10));
+    }
+
+    #[test]
+    fn test_validate() {
+        let document = PolicyDocument {
+            allowed_session_types: Some(vec![" Console".to_string(), "console".to_string(), "VIEW".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(document.validate().unwrap().allowed_session_types.unwrap(), ["console", "view"]);
+
+        let checksum = "AB".repeat(32);
+        let document = PolicyDocument {
+            allowed_tool_checksums: Some(vec![checksum.clone(), checksum.to_lowercase()]),
+            allowed_tool_categories: Some(vec!["Network".to_string()]),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap();
+        assert_eq!(document.allowed_tool_checksums.unwrap(), ["ab".repeat(32)]);
+        assert_eq!(document.allowed_tool_categories.unwrap(), ["network"]);
+
+        for invalid in [
+            PolicyDocument { max_fps: Some(0), ..Default::default() },
+            PolicyDocument { max_fps: Some(MAX_FPS_LIMIT + 1), ..Default::default() },
+            PolicyDocument { max_bandwidth_kbps: Some(8), ..Default::default() },
+            PolicyDocument { heartbeat_interval_secs: Some(1), ..Default::default() },
+            PolicyDocument { offline_after_missed_heartbeats: Some(1), ..Default::default() },
+            PolicyDocument { allowed_session_types: Some(vec!["gaming".to_string()]), ..Default::default() },
+            PolicyDocument { consent_timeout_secs: Some(0), ..Default::default() },
+            PolicyDocument { backstage_approval_ttl_secs: Some(10), ..Default::default() },
+            PolicyDocument { max_file_drop_bytes: Some(MAX_DROP_FILE_SIZE + 1), ..Default::default() },
+            PolicyDocument { allowed_tool_checksums: Some(vec!["manual".to_string()]), ..Default::default() },
+            PolicyDocument { allowed_tool_categories: Some(vec!["games".to_string()]), ..Default::default() },
+            PolicyDocument { cpu_budget_percent: Some(2), ..Default::default() },
+            PolicyDocument { memory_limit_mb: Some(16), ..Default::default() },
+            PolicyDocument { max_user_pause_hours: Some(0), ..Default::default() },
+            PolicyDocument { max_user_pause_hours: Some(24 * 30), ..Default::default() },
+        ] {
+            assert!(matches!(invalid.clone().validate(), Err(PolicyError::Invalid(_))), "{:?}", invalid);
+        }
+        assert!(serde_json::from_str::<PolicyDocument>(r#"{"max_fps": 10, "fps": 5}"#).is_err());
+        let document: PolicyDocument = serde_json::from_str(r#"{"user_disconnect": "attended", "max_user_pause_hours": 8}"#).unwrap();
+        assert_eq!(document.validate().unwrap().user_disconnect, Some(UserDisconnect::Attended));
+        assert!(serde_json::from_str::<PolicyDocument>(r#"{"user_disconnect": "never"}"#).is_err());
+        assert!(PolicyDocument::default().allows_session("backstage"));
+    }
+
+    #[test]
+    fn test_consent_for_session_types() {
+        assert_eq!(PolicyDocument::default().consent_for("console"), Consent::NotRequired);
+
+        let mut document = PolicyDocument { consent_required: Some(true), ..Default::default() };
+        assert_eq!(document.consent_for("console"), Consent::Required);
+        assert_eq!(document.consent_for("adhoc"), Consent::Required);
+        assert_eq!(document.consent_for("backstage"), Consent::Bypassed);
+
+        document.backstage_consent_bypass = Some(false);
+        assert_eq!(document.consent_for("Backstage"), Consent::Required);
+
+        let document: PolicyDocument = serde_json::from_str(r#"{"consent_required": true, "consent_timeout_action": "accept"}"#).unwrap();
+        assert_eq!(document.consent_timeout_action, Some(ConsentTimeoutAction::Accept));
+    }
+
+    #[test]
+    fn test_backstage_approval_ttl() {
+        assert_eq!(PolicyDocument::default().backstage_approval_ttl(), None);
+
+        let organization = PolicyDocument { backstage_requires_approval: Some(true), ..Default::default() };
+        assert_eq!(organization.backstage_approval_ttl(), Some(approvals::DEFAULT_TTL_SECS));
+
+        let device = PolicyDocument { backstage_approval_ttl_secs: Some(300), ..Default::default() };
+        assert_eq!(organization.overlay(&device).backstage_approval_ttl(), Some(300));
+
+        let exempt = PolicyDocument { backstage_requires_approval: Some(false), ..Default::default() };
+        assert_eq!(organization.overlay(&exempt).backstage_approval_ttl(), None);
+    }
+}
@@ -0,0 +1,662 @@
+//! Throttling of authentication attempts and relay connections
+//!
+//! Login, token refresh and the OIDC entry points draw from a token bucket
+//! per client address ([`throttle_auth`]); logins also draw from one per
+//! account. Once an account has failed [`RateLimitPolicy::lockout_threshold`]
+//! logins in a row it is locked out, for twice as long with every further
+//! failure, and the count is kept in `users.failed_login_attempts` so a
+//! restart does not reset it.
+//!
+//! Every WebSocket on `/relay/ws` and `/api/ws` holds a [`ConnectionPermit`].
+//! Permits are limited per address and overall; a refused socket is accepted
+//! and closed straight away with [`CLOSE_TOO_MANY_CONNECTIONS`] or
+//! [`CLOSE_SERVER_BUSY`], which tell clients to wait before trying again.
+//!
+//! Time comes from a [`Clock`] so tests can move it forward.
+
+use axum::{
+    async_trait,
+    extract::{ws::CloseFrame, ConnectInfo, FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// How often idle buckets and stale failure counts are forgotten
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// WebSocket close code: this address already holds as many connections as allowed
+pub const CLOSE_TOO_MANY_CONNECTIONS: u16 = 4429;
+
+/// WebSocket close code: the server is at its connection ceiling
+pub const CLOSE_SERVER_BUSY: u16 = 4503;
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The wall clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// How hard authentication and connections are throttled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitPolicy {
+    /// Authentication requests one address may make back to back; 0 turns
+    /// the per-address bucket off
+    pub ip_burst: u32,
+    /// Authentication requests an address regains per minute
+    pub ip_per_minute: u32,
+    /// Login attempts one account may take back to back; 0 turns the
+    /// per-account bucket off
+    pub account_burst: u32,
+    /// Login attempts an account regains per minute
+    pub account_per_minute: u32,
+    /// Failed logins in a row before the account is locked out; 0 never
+    /// locks accounts
+    pub lockout_threshold: u32,
+    /// Length of the first lockout, doubled with every further failure
+    pub lockout_base_secs: u64,
+    /// Longest lockout
+    pub lockout_max_secs: u64,
+    /// WebSockets one address may hold open at once
+    pub max_connections_per_ip: Option<u32>,
+    /// WebSockets the server holds open at once
+    pub max_connections: Option<u32>,
+    /// Proxies whose `X-Real-IP` or last `X-Forwarded-For` hop is taken as
+    /// the client address; requests from anywhere else are keyed on the peer
+    pub trusted_proxies: Vec<IpNetwork>,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        Self {
+            ip_burst: 20,
+            ip_per_minute: 10,
+            account_burst: 10,
+            account_per_minute: 5,
+            lockout_threshold: 5,
+            lockout_base_secs: 30,
+            lockout_max_secs: 15 * 60,
+            // Sites behind one NAT share an address
+            max_connections_per_ip: Some(200),
+            max_connections: Some(10_000),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl RateLimitPolicy {
+    /// How long an account with `failures` failed logins in a row stays locked
+    fn lockout(&self, failures: u32) -> Option<Duration> {
+        if self.lockout_threshold == 0 || failures < self.lockout_threshold {
+            return None;
+        }
+        let doublings = (failures - self.lockout_threshold).min(20);
+        let secs = self.lockout_base_secs.saturating_mul(1 << doublings).min(self.lockout_max_secs);
+        Some(Duration::seconds(secs as i64))
+    }
+}
+
+/// Why a request was turned away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Throttled {
+    /// The address or account ran out of attempts for now
+    TooManyRequests { retry_after: Duration },
+    /// The account failed too many logins
+    LockedOut { until: DateTime<Utc>, retry_after: Duration },
+}
+
+impl Throttled {
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            Throttled::TooManyRequests { retry_after } | Throttled::LockedOut { retry_after, .. } => *retry_after,
+        }
+    }
+}
+
+impl IntoResponse for Throttled {
+    fn into_response(self) -> Response {
+        // Round up so a client waiting exactly this long is let through
+        let secs = (self.retry_after().num_milliseconds().max(0) as u64).div_ceil(1000).max(1);
+        let message = match &self {
+            Throttled::TooManyRequests { .. } => "Too many requests, try again later".to_string(),
+            Throttled::LockedOut { until, .. } => format!("Too many failed logins, account locked until {}", until.to_rfc3339()),
+        };
+        let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(serde_json::json!({
+            "error": message,
+            "retry_after": secs,
+        }))).into_response();
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        response
+    }
+}
+
+/// Why a WebSocket was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionRefused {
+    TooManyFromAddress,
+    ServerBusy,
+}
+
+impl ConnectionRefused {
+    /// Close frame sent on the refused socket
+    pub fn close_frame(&self) -> CloseFrame<'static> {
+        match self {
+            ConnectionRefused::TooManyFromAddress => CloseFrame {
+                code: CLOSE_TOO_MANY_CONNECTIONS,
+                reason: "too many connections from this address".into(),
+            },
+            ConnectionRefused::ServerBusy => CloseFrame {
+                code: CLOSE_SERVER_BUSY,
+                reason: "server at capacity, retry later".into(),
+            },
+        }
+    }
+}
+
+/// Failed logins of an account after one more was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginFailure {
+    pub failures: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// Throttling counters for `/api/stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RateLimitStats {
+    /// Requests turned away for running out of attempts
+    pub throttled_requests: u64,
+    pub failed_logins: u64,
+    /// Times an account got locked out
+    pub lockouts: u64,
+    /// Accounts locked out right now
+    pub locked_accounts: usize,
+    pub open_connections: u32,
+    /// WebSockets refused for the per-address cap
+    pub refused_connections: u64,
+    /// WebSockets refused for the server-wide ceiling
+    pub busy_refusals: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: DateTime<Utc>,
+}
+
+impl TokenBucket {
+    fn full(burst: u32, now: DateTime<Utc>) -> Self {
+        Self { tokens: burst as f64, updated: now }
+    }
+
+    fn refill(&mut self, burst: u32, per_minute: u32, now: DateTime<Utc>) {
+        let elapsed = (now - self.updated).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(burst as f64);
+        self.updated = now;
+    }
+
+    /// Take one token, or say how long until there is one
+    fn take(&mut self, burst: u32, per_minute: u32, now: DateTime<Utc>) -> Result<(), Duration> {
+        self.refill(burst, per_minute, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if per_minute == 0 {
+            return Err(Duration::minutes(1));
+        }
+        let secs = (1.0 - self.tokens) * 60.0 / per_minute as f64;
+        Err(Duration::milliseconds((secs * 1000.0).ceil() as i64))
+    }
+
+    fn is_full(&mut self, burst: u32, per_minute: u32, now: DateTime<Utc>) -> bool {
+        self.refill(burst, per_minute, now);
+        self.tokens >= burst as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AccountFailures {
+    failures: u32,
+    last_failure: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    addresses: HashMap<IpAddr, TokenBucket>,
+    accounts: HashMap<String, TokenBucket>,
+    failures: HashMap<String, AccountFailures>,
+    connections: HashMap<IpAddr, u32>,
+    open_connections: u32,
+    stats: RateLimitStats,
+}
+
+/// Token buckets, lockouts and connection counts
+pub struct RateLimiter {
+    policy: RateLimitPolicy,
+    clock: Arc<dyn Clock>,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        Self::with_clock(policy, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(policy: RateLimitPolicy, clock: Arc<dyn Clock>) -> Self {
+        Self { policy, clock, state: Mutex::new(LimiterState::default()) }
+    }
+
+    pub fn policy(&self) -> &RateLimitPolicy {
+        &self.policy
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// The client a request came from: the peer, or the address a trusted
+    /// proxy forwarded for
+    pub fn client_address(&self, parts: &Parts) -> Option<IpAddr> {
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip().to_canonical());
+        let header = |name: &str| {
+            parts.headers.get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        let forwarded = || {
+            // Earlier hops are whatever the client claimed; the last one was
+            // added by our proxy
+            header("x-real-ip")
+                .or_else(|| header("x-forwarded-for").and_then(|forwarded| forwarded.rsplit(',').next()))
+                .and_then(|ip| ip.trim().parse().ok())
+        };
+        let trusted = peer.is_some_and(|peer| self.policy.trusted_proxies.iter().any(|proxy| proxy.contains(peer)));
+        trusted.then(forwarded).flatten().or(peer)
+    }
+
+    /// Take an authentication attempt from the address's bucket
+    pub fn check_address(&self, address: IpAddr) -> Result<(), Throttled> {
+        if self.policy.ip_burst == 0 {
+            return Ok(());
+        }
+        let now = self.now();
+        let mut state = self.state.lock().unwrap();
+        let (burst, per_minute) = (self.policy.ip_burst, self.policy.ip_per_minute);
+        let result = state.addresses
+            .entry(address)
+            .or_insert_with(|| TokenBucket::full(burst, now))
+            .take(burst, per_minute, now);
+        result.map_err(|retry_after| {
+            state.stats.throttled_requests += 1;
+            Throttled::TooManyRequests { retry_after }
+        })
+    }
+
+    /// Turn a login for `account` away while it is locked out or out of attempts
+    pub fn check_login(&self, account: &str) -> Result<(), Throttled> {
+        let key = account_key(account);
+        let now = self.now();
+        let mut state = self.state.lock().unwrap();
+        if let Some(until) = state.failures.get(&key).and_then(|failures| failures.locked_until) {
+            if until > now {
+                state.stats.throttled_requests += 1;
+                return Err(Throttled::LockedOut { until, retry_after: until - now });
+            }
+        }
+        if self.policy.account_burst == 0 {
+            return Ok(());
+        }
+        let (burst, per_minute) = (self.policy.account_burst, self.policy.account_per_minute);
+        let result = state.accounts
+            .entry(key)
+            .or_insert_with(|| TokenBucket::full(burst, now))
+            .take(burst, per_minute, now);
+        result.map_err(|retry_after| {
+            state.stats.throttled_requests += 1;
+            Throttled::TooManyRequests { retry_after }
+        })
+    }
+
+    /// Turn a login away while the stored lockout of the account lasts
+    pub fn check_locked_until(&self, locked_until: Option<DateTime<Utc>>) -> Result<(), Throttled> {
+        let now = self.now();
+        match locked_until {
+            Some(until) if until > now => {
+                self.state.lock().unwrap().stats.throttled_requests += 1;
+                Err(Throttled::LockedOut { until, retry_after: until - now })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a failed login; `stored_failures` is what the database last
+    /// recorded for the account, so counting resumes after a restart
+    pub fn login_failed(&self, account: &str, stored_failures: u32) -> LoginFailure {
+        let now = self.now();
+        let mut state = self.state.lock().unwrap();
+        let entry = state.failures.entry(account_key(account)).or_insert(AccountFailures {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        entry.failures = entry.failures.max(stored_failures) + 1;
+        entry.last_failure = now;
+        let lockout = self.policy.lockout(entry.failures);
+        if let Some(lockout) = lockout {
+            entry.locked_until = Some(now + lockout);
+        }
+        let failure = LoginFailure { failures: entry.failures, locked_until: entry.locked_until };
+        state.stats.failed_logins += 1;
+        if let Some(lockout) = lockout {
+            state.stats.lockouts += 1;
+            warn!("Locking out {} for {}s after {} failed logins", account, lockout.num_seconds(), failure.failures);
+        }
+        failure
+    }
+
+    /// Forget the failures of an account that logged in
+    pub fn login_succeeded(&self, account: &str) {
+        self.state.lock().unwrap().failures.remove(&account_key(account));
+    }
+
+    /// Hold a WebSocket slot for `address` until the permit is dropped
+    pub fn acquire_connection(self: &Arc<Self>, address: Option<IpAddr>) -> Result<ConnectionPermit, ConnectionRefused> {
+        let mut state = self.state.lock().unwrap();
+        if self.policy.max_connections.is_some_and(|max| state.open_connections >= max) {
+            state.stats.busy_refusals += 1;
+            return Err(ConnectionRefused::ServerBusy);
+        }
+        if let Some(address) = address {
+            let open = state.connections.get(&address).copied().unwrap_or(0);
+            if self.policy.max_connections_per_ip.is_some_and(|max| open >= max) {
+                state.stats.refused_connections += 1;
+                return Err(ConnectionRefused::TooManyFromAddress);
+            }
+            state.connections.insert(address, open + 1);
+        }
+        state.open_connections += 1;
+        Ok(ConnectionPermit { limiter: Arc::clone(self), address })
+    }
+
+    fn release_connection(&self, address: Option<IpAddr>) {
+        let mut state = self.state.lock().unwrap();
+        state.open_connections = state.open_connections.saturating_sub(1);
+        if let Some(address) = address {
+            if let Some(open) = state.connections.get_mut(&address) {
+                *open -= 1;
+                if *open == 0 {
+                    state.connections.remove(&address);
+                }
+            }
+        }
+    }
+
+    /// Forget full buckets and failures that neither lock an account nor are
+    /// recent enough to lead to a lockout
+    pub fn sweep(&self) {
+        let now = self.now();
+        let forget_after = Duration::seconds(self.policy.lockout_max_secs as i64);
+        let mut state = self.state.lock().unwrap();
+        let (ip_burst, ip_per_minute) = (self.policy.ip_burst, self.policy.ip_per_minute);
+        state.addresses.retain(|_, bucket| !bucket.is_full(ip_burst, ip_per_minute, now));
+        let (account_burst, account_per_minute) = (self.policy.account_burst, self.policy.account_per_minute);
+        state.accounts.retain(|_, bucket| !bucket.is_full(account_burst, account_per_minute, now));
+        let before = state.failures.len();
+        state.failures.retain(|_, failures| {
+            failures.locked_until.is_some_and(|until| until > now) || now - failures.last_failure < forget_after
+        });
+        if state.failures.len() < before {
+            info!("Forgot failed logins of {} accounts", before - state.failures.len());
+        }
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        let now = self.now();
+        let state = self.state.lock().unwrap();
+        RateLimitStats {
+            locked_accounts: state.failures.values()
+                .filter(|failures| failures.locked_until.is_some_and(|until| until > now))
+                .count(),
+            open_connections: state.open_connections,
+            ..state.stats.clone()
+        }
+    }
+}
+
+/// Logins are counted regardless of how the name was capitalized
+fn account_key(account: &str) -> String {
+    account.trim().to_lowercase()
+}
+
+/// A WebSocket slot, given back when dropped
+pub struct ConnectionPermit {
+    limiter: Arc<RateLimiter>,
+    address: Option<IpAddr>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release_connection(self.address);
+    }
+}
+
+/// The client address of a request, as [`RateLimiter::client_address`] sees it
+pub struct ClientAddress(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientAddress {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        Ok(Self(state.device_manager.rate_limiter.client_address(parts)))
+    }
+}
+
+/// Middleware taking every request from its address's bucket; layered onto
+/// the authentication routes
+pub async fn throttle_auth(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    let (parts, body) = request.into_parts();
+    if let Some(address) = limiter.client_address(&parts) {
+        if let Err(throttled) = limiter.check_address(address) {
+            warn!("Throttling {} on {}", address, parts.uri.path());
+            return throttled.into_response();
+        }
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::post, Router};
+    use tower::ServiceExt;
+
+    /// A clock that only moves when told to
+    struct ManualClock(Mutex<DateTime<Utc>>);
+
+    impl ManualClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self(Mutex::new(Utc::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn limiter(policy: RateLimitPolicy, clock: &Arc<ManualClock>) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::with_clock(policy, clock.clone()))
+    }
+
+    /// Address of the reverse proxy in front of the server in these tests
+    const PROXY: [u8; 4] = [10, 0, 0, 2];
+
+    fn behind_proxy(policy: RateLimitPolicy) -> RateLimitPolicy {
+        RateLimitPolicy { trusted_proxies: vec!["10.0.0.0/24".parse().unwrap()], ..policy }
+    }
+
+    async fn login_from(app: &Router, peer: [u8; 4], forwarded_for: &str) -> Response {
+        let request = axum::http::Request::post("/api/auth/login")
+            .header("x-forwarded-for", forwarded_for)
+            .extension(ConnectInfo(SocketAddr::from((peer, 40000))))
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn login(app: &Router, forwarded_for: &str) -> Response {
+        login_from(app, PROXY, forwarded_for).await
+    }
+
+    #[tokio::test]
+    async fn test_burst_allowance_then_throttled_until_refilled() {
+        let clock = ManualClock::new();
+        let policy = behind_proxy(RateLimitPolicy { ip_burst: 3, ip_per_minute: 6, ..Default::default() });
+        let limiter = limiter(policy, &clock);
+        let app = Router::new()
+            .route("/api/auth/login", post(|| async { "ok" }))
+            .route_layer(axum::middleware::from_fn_with_state(limiter.clone(), throttle_auth));
+
+        for _ in 0..3 {
+            assert_eq!(login(&app, "198.51.100.9").await.status(), StatusCode::OK);
+        }
+        let throttled = login(&app, "198.51.100.9").await;
+        assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(throttled.headers()[header::RETRY_AFTER], "10");
+
+        // Other addresses have their own bucket; a spoofed first hop doesn't
+        // pass for a different client
+        assert_eq!(login(&app, "198.51.100.10").await.status(), StatusCode::OK);
+        assert_eq!(login(&app, "10.9.9.9, 198.51.100.9").await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Six a minute is one every ten seconds
+        clock.advance(Duration::seconds(10));
+        assert_eq!(login(&app, "198.51.100.9").await.status(), StatusCode::OK);
+        assert_eq!(login(&app, "198.51.100.9").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limiter.stats().throttled_requests, 3);
+
+        // Refilled buckets are forgotten
+        clock.advance(Duration::minutes(5));
+        limiter.sweep();
+        assert!(limiter.state.lock().unwrap().addresses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_addresses_are_only_taken_from_trusted_proxies() {
+        let clock = ManualClock::new();
+        let app = |policy: RateLimitPolicy| Router::new()
+            .route("/api/auth/login", post(|| async { "ok" }))
+            .route_layer(axum::middleware::from_fn_with_state(limiter(policy, &clock), throttle_auth));
+
+        // By default every request is keyed on its peer, whatever it claims
+        let direct = app(RateLimitPolicy { ip_burst: 1, ..Default::default() });
+        assert_eq!(login_from(&direct, [198, 51, 100, 9], "203.0.113.1").await.status(), StatusCode::OK);
+        let spoofed = login_from(&direct, [198, 51, 100, 9], "203.0.113.2").await;
+        assert_eq!(spoofed.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Behind a proxy, only it may say who the client is
+        let proxied = app(behind_proxy(RateLimitPolicy { ip_burst: 1, ..Default::default() }));
+        assert_eq!(login(&proxied, "203.0.113.1").await.status(), StatusCode::OK);
+        assert_eq!(login(&proxied, "203.0.113.2").await.status(), StatusCode::OK);
+        assert_eq!(login_from(&proxied, [198, 51, 100, 9], "203.0.113.3").await.status(), StatusCode::OK);
+        let spoofed = login_from(&proxied, [198, 51, 100, 9], "203.0.113.4").await;
+        assert_eq!(spoofed.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_lockout_doubles_and_clears_on_success() {
+        let clock = ManualClock::new();
+        let policy = RateLimitPolicy { account_burst: 0, lockout_threshold: 3, lockout_base_secs: 30, lockout_max_secs: 100, ..Default::default() };
+        let limiter = limiter(policy, &clock);
+
+        for failures in 1..3 {
+            assert!(limiter.check_login("Alice").is_ok());
+            assert_eq!(limiter.login_failed("alice", 0), LoginFailure { failures, locked_until: None });
+        }
+        let failure = limiter.login_failed("alice", 0);
+        assert_eq!(failure.locked_until, Some(clock.now() + Duration::seconds(30)));
+        assert!(matches!(limiter.check_login("ALICE"), Err(Throttled::LockedOut { retry_after, .. }) if retry_after == Duration::seconds(30)));
+        assert!(limiter.check_login("bob").is_ok());
+        assert_eq!(limiter.stats().locked_accounts, 1);
+
+        // The next failure after the lockout doubles it, up to the maximum
+        clock.advance(Duration::seconds(31));
+        assert!(limiter.check_login("alice").is_ok());
+        assert_eq!(limiter.login_failed("alice", 0).locked_until, Some(clock.now() + Duration::seconds(60)));
+        clock.advance(Duration::seconds(61));
+        assert_eq!(limiter.login_failed("alice", 0).locked_until, Some(clock.now() + Duration::seconds(100)));
+
+        // Locked accounts survive a sweep, and a success clears them
+        limiter.sweep();
+        assert!(limiter.check_login("alice").is_err());
+        limiter.login_succeeded("alice");
+        assert!(limiter.check_login("alice").is_ok());
+        assert_eq!(limiter.login_failed("alice", 0).failures, 1);
+
+        // Counting picks up from what the database remembered
+        assert_eq!(limiter.login_failed("carol", 7).failures, 8);
+        let stats = limiter.stats();
+        assert_eq!((stats.lockouts, stats.failed_logins), (4, 7));
+    }
+
+    #[test]
+    fn test_account_bucket_limits_guesses_from_many_addresses() {
+        let clock = ManualClock::new();
+        let policy = RateLimitPolicy { account_burst: 2, account_per_minute: 1, ..Default::default() };
+        let limiter = limiter(policy, &clock);
+        assert!(limiter.check_login("alice").is_ok());
+        assert!(limiter.check_login("alice").is_ok());
+        assert_eq!(limiter.check_login("alice"), Err(Throttled::TooManyRequests { retry_after: Duration::seconds(60) }));
+        assert!(limiter.check_locked_until(Some(clock.now() + Duration::seconds(5))).is_err());
+        assert!(limiter.check_locked_until(Some(clock.now())).is_ok());
+    }
+
+    #[test]
+    fn test_connection_caps() {
+        let clock = ManualClock::new();
+        let policy = RateLimitPolicy { max_connections_per_ip: Some(2), max_connections: Some(3), ..Default::default() };
+        let limiter = limiter(policy, &clock);
+        let a: IpAddr = "198.51.100.1".parse().unwrap();
+        let b: IpAddr = "198.51.100.2".parse().unwrap();
+
+        let first = limiter.acquire_connection(Some(a)).unwrap();
+        let _second = limiter.acquire_connection(Some(a)).unwrap();
+        assert_eq!(limiter.acquire_connection(Some(a)).err(), Some(ConnectionRefused::TooManyFromAddress));
+        let _third = limiter.acquire_connection(Some(b)).unwrap();
+        assert_eq!(limiter.acquire_connection(Some(b)).err(), Some(ConnectionRefused::ServerBusy));
+        assert_eq!(ConnectionRefused::ServerBusy.close_frame().code, CLOSE_SERVER_BUSY);
+
+        drop(first);
+        let _fourth = limiter.acquire_connection(Some(a)).unwrap();
+        let stats = limiter.stats();
+        assert_eq!((stats.open_connections, stats.refused_connections, stats.busy_refusals), (3, 1, 1));
+    }
+}
@@ -0,0 +1,998 @@
+//! Session recordings kept on the server
+//!
+//! Agents record sessions to their own disk, where a recording is lost with
+//! the device. They upload it in chunks, while it is still being recorded
+//! or once it is finished: `POST /api/recordings` declares the recording
+//! with the size and SHA-256 of every chunk it has so far, and
+//! `PUT /api/recordings/:id/chunks/:index` sends one chunk. A chunk is only
+//! acknowledged once it matches its declaration and is stored, and
+//! declaring the recording again answers with the chunks still missing, so
+//! an interrupted upload picks up where it stopped. Both calls are signed
+//! with the device key from enrollment, like diagnostics uploads.
+//!
+//! Chunks go to an [`ObjectStore`], a local directory or an S3-compatible
+//! bucket, and the index of recordings to the database. Admins and
+//! operators list recordings by device, session, technician and date and
+//! stream them with range requests, which lets a player seek; each viewing
+//! is audited. A sweeper deletes recordings older than their organization's
+//! retention (the `recording_retention_days` setting, 0 keeping them for
+//! good), or the server's default when the organization sets none.
+
+pub mod storage;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as DbJson;
+use sqlx::FromRow;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::authz;
+use crate::auth::enrollment::UploadKind;
+use crate::auth::jwt::{error, AuthUser};
+use crate::database::DatabaseService;
+use crate::models::AuditLog;
+use crate::organizations::Tenant;
+use crate::AppState;
+
+pub use storage::{hex_digest, ObjectStore, StorageBackend, StorageError};
+
+/// How often recordings past their retention are looked for
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Largest chunk an agent may send, and the body limit of the chunk route
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Most chunks one recording may have
+const MAX_CHUNKS: usize = 100_000;
+
+/// Longest ID an agent may give a recording
+const MAX_SOURCE_ID_LENGTH: usize = 128;
+
+/// Organization setting overriding the server's retention, in days
+pub const RETENTION_SETTING: &str = "recording_retention_days";
+
+/// Where uploaded recordings are kept and for how long
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecordingConfig {
+    #[serde(default)]
+    pub storage: StorageBackend,
+    /// Days recordings are kept when their organization sets no retention;
+    /// kept for good when unset
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "VARCHAR", rename_all = "snake_case")]
+pub enum RecordingStatus {
+    /// Chunks are missing, or more may be declared
+    Uploading,
+    /// Every chunk of a finished recording arrived
+    Complete,
+}
+
+/// A chunk as the agent declares it
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DeclaredChunk {
+    pub index: u32,
+    pub size: u64,
+    /// Hex SHA-256 of the chunk
+    pub sha256: String,
+}
+
+/// A declared chunk and whether it arrived
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordingChunk {
+    pub index: u32,
+    pub size: u64,
+    pub sha256: String,
+    pub received: bool,
+}
+
+/// A recording in the index
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Recording {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub agent_id: Uuid,
+    pub organization_id: Option<Uuid>,
+    /// User who ran the session, when the relay still knew the session
+    pub technician_id: Option<Uuid>,
+    /// The agent's ID for the recording; a session may be recorded twice
+    pub source_id: String,
+    pub content_type: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub status: RecordingStatus,
+    /// The agent declared its last chunk
+    pub finished: bool,
+    /// Bytes declared so far
+    pub size: i64,
+    /// Declared chunks, by index
+    pub chunks: DbJson<Vec<RecordingChunk>>,
+    /// What the agent says about the recording, e.g. its operator and system
+    pub metadata: DbJson<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Recording {
+    /// Bytes that can be streamed: those of the chunks that arrived before
+    /// the first missing one
+    pub fn available_bytes(&self) -> u64 {
+        self.chunks.iter().take_while(|chunk| chunk.received).map(|chunk| chunk.size).sum()
+    }
+
+    fn missing(&self) -> Vec<u32> {
+        self.chunks.iter().filter(|chunk| !chunk.received).map(|chunk| chunk.index).collect()
+    }
+
+    fn state(&self, completed: bool) -> UploadState {
+        UploadState { id: self.id, status: self.status, missing: self.missing(), completed }
+    }
+
+    /// Mark it complete once it is finished and every chunk arrived;
+    /// whether that happened now
+    fn complete_if_done(&mut self) -> bool {
+        if self.status == RecordingStatus::Uploading && self.finished && self.chunks.iter().all(|chunk| chunk.received) {
+            self.status = RecordingStatus::Complete;
+            return true;
+        }
+        false
+    }
+
+    /// Store key of a chunk
+    fn chunk_key(&self, index: u32) -> String {
+        format!("{}/{:06}", self.id, index)
+    }
+}
+
+/// Body of `POST /api/recordings`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordingUpload {
+    pub agent_id: Uuid,
+    pub session_id: Uuid,
+    pub source_id: String,
+    pub started_at: DateTime<Utc>,
+    #[serde(default)]
+    pub ended_at: Option<DateTime<Utc>>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub metadata: serde_json::Value,
+    /// Chunks recorded so far; earlier declarations need not be repeated
+    #[serde(default)]
+    pub chunks: Vec<DeclaredChunk>,
+    /// No chunks follow those declared
+    #[serde(default, rename = "final")]
+    pub finished: bool,
+}
+
+fn default_content_type() -> String {
+    "application/octet-stream".to_string()
+}
+
+/// Where an upload stands, as the agent is told after every call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UploadState {
+    pub id: Uuid,
+    pub status: RecordingStatus,
+    /// Declared chunks that have not arrived
+    pub missing: Vec<u32>,
+    /// This call completed the recording
+    #[serde(skip)]
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordingError {
+    NotFound,
+    Invalid(String),
+    /// The upload contradicts what was declared before
+    Conflict(String),
+    /// A chunk does not match its declared size and SHA-256
+    ChecksumMismatch(u32),
+    Storage(StorageError),
+}
+
+impl std::fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingError::NotFound => write!(f, "Recording not found"),
+            RecordingError::Invalid(message) | RecordingError::Conflict(message) => f.write_str(message),
+            RecordingError::ChecksumMismatch(index) => {
+                write!(f, "Chunk {} does not match its declared size and SHA-256", index)
+            }
+            RecordingError::Storage(e) => e.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for RecordingError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            RecordingError::NotFound => StatusCode::NOT_FOUND,
+            RecordingError::Invalid(_) => StatusCode::BAD_REQUEST,
+            RecordingError::Conflict(_) => StatusCode::CONFLICT,
+            RecordingError::ChecksumMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RecordingError::Storage(_) => StatusCode::BAD_GATEWAY,
+        };
+        error(status, self.to_string())
+    }
+}
+
+fn is_sha256(hex: &str) -> bool {
+    hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Criteria for listing recordings
+#[derive(Debug, Default, Clone)]
+pub struct RecordingFilter {
+    pub tenant: Tenant,
+    pub agent_id: Option<Uuid>,
+    pub session_id: Option<Uuid>,
+    pub technician_id: Option<Uuid>,
+    /// Only recordings started at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only recordings started before this time
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl RecordingFilter {
+    fn matches(&self, recording: &Recording) -> bool {
+        self.tenant.includes(recording.organization_id)
+            && self.agent_id.is_none_or(|agent_id| recording.agent_id == agent_id)
+            && self.session_id.is_none_or(|session_id| recording.session_id == session_id)
+            && self.technician_id.is_none_or(|technician_id| recording.technician_id == Some(technician_id))
+            && self.from.is_none_or(|from| recording.started_at >= from)
+            && self.to.is_none_or(|to| recording.started_at < to)
+    }
+}
+
+/// Retention an organization's settings ask for, in days
+pub fn retention_override(settings: &HashMap<String, serde_json::Value>) -> Option<u32> {
+    settings.get(RETENTION_SETTING)?.as_u64().map(|days| days.min(u32::MAX as u64) as u32)
+}
+
+/// Uploaded recordings: the index and the store holding their chunks
+pub struct RecordingManager {
+    store: Arc<dyn ObjectStore>,
+    db: Option<Arc<DatabaseService>>,
+    recordings: RwLock<HashMap<Uuid, Recording>>,
+    /// Days recordings are kept when their organization sets nothing;
+    /// `None` keeps them
+    default_retention_days: Option<u32>,
+}
+
+impl RecordingManager {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            db: None,
+            recordings: RwLock::new(HashMap::new()),
+            default_retention_days: None,
+        }
+    }
+
+    /// Index recordings in `db`
+    pub fn with_database(mut self, db: Arc<DatabaseService>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Delete recordings after `days` unless their organization says otherwise
+    pub fn with_default_retention(mut self, days: Option<u32>) -> Self {
+        self.default_retention_days = days.filter(|days| *days > 0);
+        self
+    }
+
+    /// Load the index a previous run stored
+    pub async fn initialize(&self) -> Result<(), String> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let recordings = db.list_recordings().await.map_err(|e| format!("Failed to load recordings: {}", e))?;
+        info!("Loaded {} session recordings", recordings.len());
+        self.recordings.write().await.extend(recordings.into_iter().map(|recording| (recording.id, recording)));
+        Ok(())
+    }
+
+    async fn persist(&self, recording: &Recording) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.upsert_recording(recording).await {
+                warn!("Failed to store recording {}: {}", recording.id, e);
+            }
+        }
+    }
+
+    /// Declare a recording, or more chunks of one declared before
+    pub async fn begin(
+        &self,
+        upload: RecordingUpload,
+        organization_id: Option<Uuid>,
+        technician_id: Option<Uuid>,
+        now: DateTime<Utc>,
+    ) -> Result<UploadState, RecordingError> {
+        if upload.source_id.is_empty() || upload.source_id.len() > MAX_SOURCE_ID_LENGTH {
+            return Err(RecordingError::Invalid(format!(
+                "source_id must have 1 to {} characters", MAX_SOURCE_ID_LENGTH
+            )));
+        }
+        for chunk in &upload.chunks {
+            if !is_sha256(&chunk.sha256) {
+                return Err(RecordingError::Invalid(format!("Chunk {} needs a hex SHA-256", chunk.index)));
+            }
+            if chunk.size == 0 || chunk.size > MAX_CHUNK_SIZE as u64 {
+                return Err(RecordingError::Invalid(format!(
+                    "Chunk {} must have 1 to {} bytes", chunk.index, MAX_CHUNK_SIZE
+                )));
+            }
+        }
+
+        let mut all = self.recordings.write().await;
+        let existing = all.values().find(|recording| {
+            (recording.agent_id, recording.session_id, &recording.source_id)
+                == (upload.agent_id, upload.session_id, &upload.source_id)
+        });
+        let mut recording = match existing {
+            Some(recording) => recording.clone(),
+            None => Recording {
+                id: Uuid::new_v4(),
+                session_id: upload.session_id,
+                agent_id: upload.agent_id,
+                organization_id,
+                technician_id,
+                source_id: upload.source_id.clone(),
+                content_type: upload.content_type.clone(),
+                started_at: upload.started_at,
+                ended_at: None,
+                status: RecordingStatus::Uploading,
+                finished: false,
+                size: 0,
+                chunks: DbJson(Vec::new()),
+                metadata: DbJson(serde_json::json!({})),
+                created_at: now,
+                updated_at: now,
+            },
+        };
+
+        for declared in &upload.chunks {
+            let sha256 = declared.sha256.to_ascii_lowercase();
+            match recording.chunks.iter().find(|chunk| chunk.index == declared.index) {
+                Some(chunk) if (chunk.size, &chunk.sha256) == (declared.size, &sha256) => {}
+                Some(_) => {
+                    return Err(RecordingError::Conflict(format!(
+                        "Chunk {} was declared before with another size or SHA-256", declared.index
+                    )));
+                }
+                None if recording.finished => {
+                    return Err(RecordingError::Conflict(format!(
+                        "Recording {} is final; chunk {} is past its end", recording.id, declared.index
+                    )));
+                }
+                None => recording.chunks.push(RecordingChunk {
+                    index: declared.index,
+                    size: declared.size,
+                    sha256,
+                    received: false,
+                }),
+            }
+        }
+        if recording.chunks.len() > MAX_CHUNKS {
+            return Err(RecordingError::Invalid(format!("A recording may have at most {} chunks", MAX_CHUNKS)));
+        }
+        recording.chunks.sort_by_key(|chunk| chunk.index);
+        if upload.finished {
+            let contiguous = recording.chunks.iter().enumerate().all(|(position, chunk)| chunk.index as usize == position);
+            if !contiguous {
+                return Err(RecordingError::Invalid(
+                    "A final recording must declare chunks 0 to n without gaps".to_string(),
+                ));
+            }
+            recording.finished = true;
+        }
+
+        recording.size = recording.chunks.iter().map(|chunk| chunk.size as i64).sum();
+        if upload.ended_at.is_some() {
+            recording.ended_at = upload.ended_at;
+        }
+        if !upload.metadata.is_null() {
+            recording.metadata = DbJson(upload.metadata);
+        }
+        recording.updated_at = now;
+        let completed = recording.complete_if_done();
+        all.insert(recording.id, recording.clone());
+        drop(all);
+
+        self.persist(&recording).await;
+        Ok(recording.state(completed))
+    }
+
+    /// Store a chunk once it matches its declaration
+    pub async fn put_chunk(&self, id: Uuid, index: u32, data: Bytes, now: DateTime<Utc>) -> Result<UploadState, RecordingError> {
+        let (key, declared) = {
+            let all = self.recordings.read().await;
+            let recording = all.get(&id).ok_or(RecordingError::NotFound)?;
+            let chunk = recording.chunks.iter().find(|chunk| chunk.index == index)
+                .ok_or_else(|| RecordingError::Invalid(format!("Chunk {} was not declared", index)))?;
+            (recording.chunk_key(index), chunk.clone())
+        };
+        if data.len() as u64 != declared.size || hex_digest(&data) != declared.sha256 {
+            return Err(RecordingError::ChecksumMismatch(index));
+        }
+        self.store.put(&key, data).await.map_err(RecordingError::Storage)?;
+
+        let mut all = self.recordings.write().await;
+        // Expired while the chunk was stored
+        let Some(recording) = all.get_mut(&id) else {
+            drop(all);
+            let _ = self.store.delete(&key).await;
+            return Err(RecordingError::NotFound);
+        };
+        if let Some(chunk) = recording.chunks.iter_mut().find(|chunk| chunk.index == index) {
+            chunk.received = true;
+        }
+        recording.updated_at = now;
+        let completed = recording.complete_if_done();
+        let recording = recording.clone();
+        drop(all);
+
+        if completed {
+            info!("Recording {} of session {} is complete ({} bytes)", recording.id, recording.session_id, recording.size);
+        }
+        self.persist(&recording).await;
+        Ok(recording.state(completed))
+    }
+
+    pub async fn get(&self, id: Uuid) -> Option<Recording> {
+        self.recordings.read().await.get(&id).cloned()
+    }
+
+    /// Recordings matching `filter`, newest first
+    pub async fn list(&self, filter: &RecordingFilter) -> Vec<Recording> {
+        let mut matching: Vec<Recording> = self.recordings.read().await
+            .values()
+            .filter(|recording| filter.matches(recording))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| b.started_at.cmp(&a.started_at).then_with(|| a.id.cmp(&b.id)));
+        matching
+    }
+
+    /// Bytes `range` of a recording, read chunk by chunk as they are sent
+    pub fn stream(&self, recording: &Recording, range: Range<u64>) -> Body {
+        let mut pieces = Vec::new();
+        let mut offset = 0;
+        for chunk in recording.chunks.iter() {
+            let chunk_range = offset..offset + chunk.size;
+            offset = chunk_range.end;
+            let start = range.start.max(chunk_range.start);
+            let end = range.end.min(chunk_range.end);
+            if start < end {
+                pieces.push((recording.chunk_key(chunk.index), start - chunk_range.start..end - chunk_range.start));
+            }
+        }
+
+        let store = self.store.clone();
+        let stream = futures_util::stream::iter(pieces).then(move |(key, range)| {
+            let store = store.clone();
+            async move { store.get_range(&key, range).await.map_err(std::io::Error::other) }
+        });
+        Body::from_stream(stream)
+    }
+
+    /// Delete recordings older than their retention: `overrides` by
+    /// organization, 0 keeping them, and the default otherwise. Those
+    /// deleted come back; one whose chunks could not all be deleted stays
+    /// for the next sweep.
+    pub async fn expire(&self, now: DateTime<Utc>, overrides: &HashMap<Uuid, u32>) -> Vec<Recording> {
+        let expired: Vec<Recording> = self.recordings.read().await
+            .values()
+            .filter(|recording| {
+                let days = recording.organization_id
+                    .and_then(|organization_id| overrides.get(&organization_id).copied())
+                    .or(self.default_retention_days);
+                days.is_some_and(|days| days > 0 && recording.started_at + Duration::days(days as i64) <= now)
+            })
+            .cloned()
+            .collect();
+
+        let mut deleted = Vec::new();
+        for recording in expired {
+            let mut failed = false;
+            for chunk in recording.chunks.iter() {
+                if let Err(e) = self.store.delete(&recording.chunk_key(chunk.index)).await {
+                    warn!("Failed to delete chunk {} of recording {}: {}", chunk.index, recording.id, e);
+                    failed = true;
+                }
+            }
+            if failed {
+                continue;
+            }
+            self.recordings.write().await.remove(&recording.id);
+            if let Some(db) = &self.db {
+                if let Err(e) = db.delete_recording(recording.id).await {
+                    warn!("Failed to delete recording {} from the index: {}", recording.id, e);
+                }
+            }
+            deleted.push(recording);
+        }
+        deleted
+    }
+}
+
+/// The range a `Range` header asks of `length` bytes: `Ok(None)` for the
+/// whole of it, including for headers that are malformed or ask for
+/// several ranges, which may be ignored; `Err` when nothing of it is there
+pub fn parse_range(header: Option<&str>, length: u64) -> Result<Option<Range<u64>>, ()> {
+    let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    if start.is_empty() {
+        // The last `end` bytes
+        let Ok(suffix) = end.parse::<u64>() else { return Ok(None) };
+        if suffix == 0 || length == 0 {
+            return Err(());
+        }
+        return Ok(Some(length.saturating_sub(suffix)..length));
+    }
+    let Ok(start) = start.parse::<u64>() else { return Ok(None) };
+    let end = match end {
+        "" => length,
+        end => match end.parse::<u64>() {
+            Ok(end) if end >= start => (end + 1).min(length),
+            _ => return Ok(None),
+        },
+    };
+    if start >= length {
+        return Err(());
+    }
+    Ok(Some(start..end))
+}
+
+/// A recording's bytes as `range_header` asks for them
+pub fn range_response(manager: &RecordingManager, recording: &Recording, range_header: Option<&str>) -> Response {
+    let length = recording.available_bytes();
+    let range = match parse_range(range_header, length) {
+        Ok(range) => range,
+        Err(()) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", length))],
+            ).into_response();
+        }
+    };
+
+    let (status, range) = match range {
+        Some(range) => (StatusCode::PARTIAL_CONTENT, range),
+        None => (StatusCode::OK, 0..length),
+    };
+    let mut headers = vec![
+        (header::CONTENT_TYPE, recording.content_type.clone()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::CONTENT_LENGTH, (range.end - range.start).to_string()),
+    ];
+    if status == StatusCode::PARTIAL_CONTENT {
+        headers.push((header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, length)));
+    }
+    let body = manager.stream(recording, range);
+    let mut response = (status, body).into_response();
+    for (name, value) in headers {
+        if let Ok(value) = value.parse() {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Audit a recording that just became complete
+async fn audit_completed(app_state: &AppState, state: &UploadState) {
+    if !state.completed {
+        return;
+    }
+    let Some(recording) = app_state.device_manager.recordings.get(state.id).await else { return };
+    app_state.device_manager.record_audit(
+        AuditLog::new("recording", "recording_uploaded")
+            .actor(recording.agent_id.to_string())
+            .agent(recording.agent_id)
+            .session(recording.session_id)
+            .details(serde_json::json!({
+                "recording": recording.id,
+                "size": recording.size,
+                "chunks": recording.chunks.len(),
+            })),
+    ).await;
+}
+
+/// Declare a recording or more of its chunks; answers with the chunks
+/// still missing
+pub async fn api_begin_upload(State(app_state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+    let upload: RecordingUpload = match serde_json::from_slice(&body) {
+        Ok(upload) => upload,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("Invalid recording upload: {}", e)),
+    };
+    if let Err(response) = app_state.enrollment.verify_signed_upload(&headers, upload.agent_id, &body, UploadKind::Recording).await {
+        return response;
+    }
+
+    let device_manager = &app_state.device_manager;
+    let organization_id = device_manager.get_agent(upload.agent_id).await.and_then(|(agent, _)| agent.organization_id);
+    let technician_id = match device_manager.get_session(upload.session_id).await {
+        Some(session) if session.agent_id != upload.agent_id => {
+            return error(StatusCode::BAD_REQUEST, format!("Session {} is not one of this device's", upload.session_id));
+        }
+        Some(session) => Some(session.user_id),
+        None => None,
+    };
+    match device_manager.recordings.begin(upload, organization_id, technician_id, Utc::now()).await {
+        Ok(state) => {
+            audit_completed(&app_state, &state).await;
+            Json(state).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Accept one chunk of a declared recording
+pub async fn api_upload_chunk(
+    State(app_state): State<AppState>,
+    Path((id, index)): Path<(Uuid, u32)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let recordings = &app_state.device_manager.recordings;
+    let Some(recording) = recordings.get(id).await else {
+        return RecordingError::NotFound.into_response();
+    };
+    if let Err(response) = app_state.enrollment.verify_signed_upload(&headers, recording.agent_id, &body, UploadKind::Recording).await {
+        return response;
+    }
+    match recordings.put_chunk(id, index, body, Utc::now()).await {
+        Ok(state) => {
+            audit_completed(&app_state, &state).await;
+            Json(state).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Filters of `GET /api/recordings`
+#[derive(Debug, Deserialize)]
+pub struct RecordingQuery {
+    pub device: Option<Uuid>,
+    pub session: Option<Uuid>,
+    pub technician: Option<Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Recordings of the caller's organization, newest first
+pub async fn api_list_recordings(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<RecordingQuery>,
+) -> Response {
+    let filter = RecordingFilter {
+        tenant,
+        agent_id: query.device,
+        session_id: query.session,
+        technician_id: query.technician,
+        from: query.from,
+        to: query.to,
+    };
+    let recordings = app_state.device_manager.recordings.list(&filter).await;
+    Json(serde_json::json!({ "recordings": recordings })).into_response()
+}
+
+/// A recording the caller may watch, audited as `action`
+async fn viewable(
+    app_state: &AppState,
+    user: &AuthUser,
+    context: &RequestContext,
+    id: Uuid,
+    action: &str,
+) -> Result<Recording, Response> {
+    let tenant = Tenant::of(user).map_err(IntoResponse::into_response)?;
+    let recording = match app_state.device_manager.recordings.get(id).await {
+        Some(recording) if tenant.includes(recording.organization_id) => recording,
+        _ => return Err(RecordingError::NotFound.into_response()),
+    };
+    authz::check_agent_permission(app_state, user, recording.agent_id, false).await
+        .map_err(IntoResponse::into_response)?;
+
+    app_state.device_manager.record_audit(
+        AuditLog::new("recording", action)
+            .actor(user.email.clone())
+            .agent(recording.agent_id)
+            .session(recording.session_id)
+            .details(serde_json::json!({ "recording": recording.id }))
+            .request(context),
+    ).await;
+    Ok(recording)
+}
+
+/// What is known about a recording and how much of it can be played
+pub async fn api_get_recording_meta(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(id): Path<Uuid>,
+) -> Response {
+    match viewable(&app_state, &user, &context, id, "recording_inspected").await {
+        Ok(recording) => {
+            let available_bytes = recording.available_bytes();
+            let mut body = serde_json::json!(recording);
+            body["available_bytes"] = serde_json::json!(available_bytes);
+            Json(body).into_response()
+        }
+        Err(response) => response,
+    }
+}
+
+/// A recording's bytes, whole or the range a player asks for
+pub async fn api_stream_recording(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Response {
+    let range = headers.get(header::RANGE).and_then(|value| value.to_str().ok());
+    // A player seeks with many requests; only the one from the start is a viewing
+    let action = match parse_range(range, u64::MAX) {
+        Ok(Some(range)) if range.start > 0 => None,
+        _ => Some("recording_viewed"),
+    };
+    let recording = match action {
+        Some(action) => match viewable(&app_state, &user, &context, id, action).await {
+            Ok(recording) => recording,
+            Err(response) => return response,
+        },
+        None => {
+            let tenant = match Tenant::of(&user) {
+                Ok(tenant) => tenant,
+                Err(e) => return e.into_response(),
+            };
+            match app_state.device_manager.recordings.get(id).await {
+                Some(recording) if tenant.includes(recording.organization_id) => {
+                    if let Err(e) = authz::check_agent_permission(&app_state, &user, recording.agent_id, false).await {
+                        return e.into_response();
+                    }
+                    recording
+                }
+                _ => return RecordingError::NotFound.into_response(),
+            }
+        }
+    };
+    range_response(&app_state.device_manager.recordings, &recording, range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::LocalStore;
+
+    fn manager() -> (RecordingManager, std::path::PathBuf) {
+        let root = std::env::temp_dir().join(format!("ghostlink-recordings-{}", Uuid::new_v4()));
+        (RecordingManager::new(Arc::new(LocalStore::new(root.clone()))), root)
+    }
+
+    fn declare(index: u32, data: &[u8]) -> DeclaredChunk {
+        DeclaredChunk { index, size: data.len() as u64, sha256: hex_digest(data) }
+    }
+
+    fn upload(agent_id: Uuid, session_id: Uuid, chunks: Vec<DeclaredChunk>, finished: bool) -> RecordingUpload {
+        RecordingUpload {
+            agent_id,
+            session_id,
+            source_id: "rec-1".to_string(),
+            started_at: Utc::now(),
+            ended_at: None,
+            content_type: "application/vnd.ghostlink.recording".to_string(),
+            metadata: serde_json::json!({ "operator": "tech@example.com" }),
+            chunks,
+            finished,
+        }
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_upload_resumes_and_verifies_chunks() {
+        let (recordings, root) = manager();
+        let (agent, session, now) = (Uuid::new_v4(), Uuid::new_v4(), Utc::now());
+        let chunks: [&[u8]; 3] = [b"first chunk", b"second", b"third and last"];
+
+        // Recorded so far: two chunks
+        let state = recordings.begin(upload(agent, session, vec![declare(0, chunks[0]), declare(1, chunks[1])], false), None, None, now).await.unwrap();
+        assert_eq!(state.missing, vec![0, 1]);
+        let id = state.id;
+
+        assert_eq!(
+            recordings.put_chunk(id, 0, Bytes::from_static(b"first chunK"), now).await,
+            Err(RecordingError::ChecksumMismatch(0))
+        );
+        assert_eq!(recordings.put_chunk(id, 0, Bytes::from_static(chunks[0]), now).await.unwrap().missing, vec![1]);
+        assert!(matches!(recordings.put_chunk(id, 7, Bytes::from_static(chunks[1]), now).await, Err(RecordingError::Invalid(_))));
+
+        // The upload broke off; declaring it again finds the same recording
+        // and what is still missing
+        let state = recordings.begin(upload(agent, session, vec![declare(1, chunks[1]), declare(2, chunks[2])], true), None, None, now).await.unwrap();
+        assert_eq!((state.id, state.missing.clone(), state.status), (id, vec![1, 2], RecordingStatus::Uploading));
+        assert_eq!(recordings.get(id).await.unwrap().available_bytes(), chunks[0].len() as u64);
+
+        recordings.put_chunk(id, 2, Bytes::from_static(chunks[2]), now).await.unwrap();
+        let state = recordings.put_chunk(id, 1, Bytes::from_static(chunks[1]), now).await.unwrap();
+        assert!(state.completed);
+        assert_eq!(state.status, RecordingStatus::Complete);
+        assert!(state.missing.is_empty());
+
+        // Sending a chunk again changes nothing
+        assert!(!recordings.put_chunk(id, 1, Bytes::from_static(chunks[1]), now).await.unwrap().completed);
+
+        let recording = recordings.get(id).await.unwrap();
+        assert_eq!(recording.size, chunks.concat().len() as i64);
+        assert_eq!(body(range_response(&recordings, &recording, None)).await, chunks.concat());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_contradicting_declarations_are_refused() {
+        let (recordings, root) = manager();
+        let (agent, session, now) = (Uuid::new_v4(), Uuid::new_v4(), Utc::now());
+        recordings.begin(upload(agent, session, vec![declare(0, b"zero"), declare(1, b"one")], true), None, None, now).await.unwrap();
+
+        let changed = upload(agent, session, vec![declare(1, b"uno")], false);
+        assert!(matches!(recordings.begin(changed, None, None, now).await, Err(RecordingError::Conflict(_))));
+        let past_end = upload(agent, session, vec![declare(2, b"two")], false);
+        assert!(matches!(recordings.begin(past_end, None, None, now).await, Err(RecordingError::Conflict(_))));
+
+        let gap = RecordingUpload { source_id: "rec-2".to_string(), ..upload(agent, session, vec![declare(1, b"one")], true) };
+        assert!(matches!(recordings.begin(gap, None, None, now).await, Err(RecordingError::Invalid(_))));
+        let unhashed = upload(agent, session, vec![DeclaredChunk { index: 0, size: 4, sha256: "abc".to_string() }], false);
+        assert!(matches!(recordings.begin(unhashed, None, None, now).await, Err(RecordingError::Invalid(_))));
+
+        assert_eq!(recordings.list(&RecordingFilter::default()).await.len(), 1);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_list_filters_by_device_technician_date_and_tenant() {
+        let (recordings, root) = manager();
+        let (agent, other_agent, technician, org) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let mut old = upload(agent, Uuid::new_v4(), Vec::new(), false);
+        old.started_at = now - Duration::days(3);
+        recordings.begin(old, Some(org), Some(technician), now).await.unwrap();
+        recordings.begin(upload(agent, Uuid::new_v4(), Vec::new(), false), Some(org), None, now).await.unwrap();
+        recordings.begin(upload(other_agent, Uuid::new_v4(), Vec::new(), false), None, Some(technician), now).await.unwrap();
+
+        let recordings = &recordings;
+        let count = |filter: RecordingFilter| async move { recordings.list(&filter).await.len() };
+        assert_eq!(count(RecordingFilter::default()).await, 3);
+        assert_eq!(count(RecordingFilter { agent_id: Some(agent), ..Default::default() }).await, 2);
+        assert_eq!(count(RecordingFilter { technician_id: Some(technician), ..Default::default() }).await, 2);
+        assert_eq!(count(RecordingFilter { from: Some(now - Duration::days(1)), ..Default::default() }).await, 2);
+        assert_eq!(count(RecordingFilter { to: Some(now - Duration::days(1)), ..Default::default() }).await, 1);
+        assert_eq!(count(RecordingFilter { tenant: Tenant::Organization(Some(org)), ..Default::default() }).await, 2);
+        assert_eq!(count(RecordingFilter { tenant: Tenant::Organization(Some(Uuid::new_v4())), ..Default::default() }).await, 0);
+
+        let newest_first = recordings.list(&RecordingFilter { agent_id: Some(agent), ..Default::default() }).await;
+        assert!(newest_first[0].started_at > newest_first[1].started_at);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_retention_deletes_old_recordings_and_their_chunks() {
+        let (recordings, root) = manager();
+        let recordings = recordings.with_default_retention(Some(30));
+        let (strict, lenient, forever) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+
+        let mut ids = HashMap::new();
+        for (organization_id, age_days) in [(None, 40), (None, 10), (Some(strict), 10), (Some(lenient), 40), (Some(forever), 4000)] {
+            let mut recording = upload(Uuid::new_v4(), Uuid::new_v4(), vec![declare(0, b"frames")], true);
+            recording.started_at = now - Duration::days(age_days);
+            let id = recordings.begin(recording, organization_id, None, now).await.unwrap().id;
+            recordings.put_chunk(id, 0, Bytes::from_static(b"frames"), now).await.unwrap();
+            ids.insert((organization_id, age_days), id);
+        }
+
+        let overrides = HashMap::from([(strict, 7), (lenient, 90), (forever, 0)]);
+        let mut deleted: Vec<Uuid> = recordings.expire(now, &overrides).await.iter().map(|recording| recording.id).collect();
+        deleted.sort();
+        let mut expected = vec![ids[&(None, 40)], ids[&(Some(strict), 10)]];
+        expected.sort();
+        assert_eq!(deleted, expected);
+
+        for id in &expected {
+            assert!(recordings.get(*id).await.is_none());
+            assert!(!root.join(id.to_string()).exists());
+        }
+        assert_eq!(recordings.list(&RecordingFilter::default()).await.len(), 3);
+        assert!(root.join(ids[&(Some(forever), 4000)].to_string()).join("000000").exists());
+        assert!(recordings.expire(now, &overrides).await.is_empty());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 100), Ok(None));
+        assert_eq!(parse_range(Some("bytes=0-9"), 100), Ok(Some(0..10)));
+        assert_eq!(parse_range(Some("bytes=90-"), 100), Ok(Some(90..100)));
+        assert_eq!(parse_range(Some("bytes=90-1000"), 100), Ok(Some(90..100)));
+        assert_eq!(parse_range(Some("bytes=-10"), 100), Ok(Some(90..100)));
+        assert_eq!(parse_range(Some("bytes=-1000"), 100), Ok(Some(0..100)));
+        assert_eq!(parse_range(Some("bytes=100-"), 100), Err(()));
+        assert_eq!(parse_range(Some("bytes=-0"), 100), Err(()));
+        assert_eq!(parse_range(Some("bytes=0-"), 0), Err(()));
+        // Ignored rather than refused
+        for ignored in ["items=0-9", "bytes=0-9,20-29", "bytes=9-0", "bytes=x-9", "bytes=5"] {
+            assert_eq!(parse_range(Some(ignored), 100), Ok(None), "{}", ignored);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_responses_span_chunks() {
+        let (recordings, root) = manager();
+        let now = Utc::now();
+        let chunks: [&[u8]; 3] = [b"0123", b"4567", b"89"];
+        let declared = chunks.iter().enumerate().map(|(index, data)| declare(index as u32, data)).collect();
+        let id = recordings.begin(upload(Uuid::new_v4(), Uuid::new_v4(), declared, true), None, None, now).await.unwrap().id;
+        recordings.put_chunk(id, 0, Bytes::from_static(chunks[0]), now).await.unwrap();
+        recordings.put_chunk(id, 1, Bytes::from_static(chunks[1]), now).await.unwrap();
+        let partial = recordings.get(id).await.unwrap();
+
+        let whole = range_response(&recordings, &partial, None);
+        assert_eq!(whole.status(), StatusCode::OK);
+        assert_eq!(whole.headers()[header::ACCEPT_RANGES], "bytes");
+        // Only the chunks before the missing one can be played yet
+        assert_eq!(whole.headers()[header::CONTENT_LENGTH], "8");
+        assert_eq!(body(whole).await, b"01234567");
+
+        recordings.put_chunk(id, 2, Bytes::from_static(chunks[2]), now).await.unwrap();
+        let recording = recordings.get(id).await.unwrap();
+
+        let across = range_response(&recordings, &recording, Some("bytes=2-8"));
+        assert_eq!(across.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(across.headers()[header::CONTENT_RANGE], "bytes 2-8/10");
+        assert_eq!(across.headers()[header::CONTENT_LENGTH], "7");
+        assert_eq!(body(across).await, b"2345678");
+
+        let tail = range_response(&recordings, &recording, Some("bytes=-3"));
+        assert_eq!(tail.headers()[header::CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(body(tail).await, b"789");
+
+        let open = range_response(&recordings, &recording, Some("bytes=4-"));
+        assert_eq!(body(open).await, b"456789");
+
+        let beyond = range_response(&recordings, &recording, Some("bytes=10-"));
+        assert_eq!(beyond.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(beyond.headers()[header::CONTENT_RANGE], "bytes */10");
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
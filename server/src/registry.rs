@@ -0,0 +1,439 @@
+//! Remote registry editing for Windows agents.
+//!
+//! The agent enforces its own path allowlist; the server additionally requires
+//! control rights and, for writes outside HKEY_CURRENT_USER, an approved
+//! elevation for the session. Every change lands in the audit trail with the
+//! data before and after, as the agent reported it.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::authz::{self, check_role, user_role, RouteClass};
+use crate::auth::jwt::AuthUser;
+use crate::device_manager::DeviceManager;
+use crate::models::{AuditLog, Session};
+use crate::organizations::{self, Tenant};
+use crate::AppState;
+
+/// Operations that only read, and are not audited
+const READ_OPERATIONS: &[&str] = &["query", "export"];
+
+#[derive(Debug, Deserialize)]
+pub struct RegistryKeyQuery {
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistryValueQuery {
+    pub path: String,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateKeyRequest {
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetValueRequest {
+    pub path: String,
+    #[serde(default)]
+    pub name: String,
+    pub value: serde_json::Value,
+}
+
+/// Whether writing below `path` needs administrative rights on the agent
+pub fn requires_admin(path: &str) -> bool {
+    let hive = path.trim()
+        .split(['\\', '/'])
+        .find(|c| !c.is_empty())
+        .unwrap_or("")
+        .to_uppercase();
+    !matches!(hive.as_str(), "HKCU" | "HKEY_CURRENT_USER")
+}
+
+fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(serde_json::json!({
+        "error": message.into()
+    }))).into_response()
+}
+
+/// Check control rights over the session's device and, for admin paths, an
+/// approved elevation. Returns the session and the elevation request covering
+/// the operation, if one was needed.
+async fn authorize(
+    app_state: &AppState,
+    user: &AuthUser,
+    session_id: Uuid,
+    path: &str,
+    write: bool,
+) -> Result<(Session, Option<Uuid>), Response> {
+    user_role(user)
+        .and_then(|role| check_role(&role, RouteClass::SessionControl))
+        .map_err(IntoResponse::into_response)?;
+    let tenant = Tenant::of(user).map_err(IntoResponse::into_response)?;
+    let session = app_state.device_manager.get_session(session_id).await
+        .filter(|session| tenant.includes(session.organization_id))
+        .ok_or_else(|| organizations::session_not_found(session_id).into_response())?;
+    authz::check_agent_permission(app_state, user, session.agent_id, true)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    if !write || !requires_admin(path) {
+        return Ok((session, None));
+    }
+
+    match app_state.device_manager.pam_manager.approved_elevation_for_session(session_id).await {
+        Some(elevation_id) => Ok((session, Some(elevation_id))),
+        None => Err(error_response(
+            StatusCode::FORBIDDEN,
+            format!("Modifying {} requires an approved elevation for this session", path),
+        )),
+    }
+}
+
+/// Audit details of a change and how it went, taking the data before and
+/// after from the agent's own audit entry; `None` for reads
+pub fn change_details(
+    operation: &serde_json::Value,
+    result: &Result<serde_json::Value, String>,
+) -> Option<serde_json::Value> {
+    let op = operation.get("op").and_then(|v| v.as_str()).unwrap_or_default();
+    if READ_OPERATIONS.contains(&op) {
+        return None;
+    }
+
+    let field = |value: &serde_json::Value, name: &str| value.get(name).cloned().unwrap_or(serde_json::Value::Null);
+    let (success, error, audit) = match result {
+        Ok(result) => (
+            result.get("success").and_then(|v| v.as_bool()).unwrap_or(false),
+            field(result, "error"),
+            field(result, "audit"),
+        ),
+        Err(e) => (false, serde_json::Value::String(e.clone()), serde_json::Value::Null),
+    };
+    let mut details = serde_json::json!({
+        "operation": op,
+        "path": field(operation, "path"),
+        "before": field(&audit, "before"),
+        "after": field(&audit, "after"),
+        "success": success,
+    });
+    if let Some(name) = operation.get("name") {
+        details["name"] = name.clone();
+    }
+    if !error.is_null() {
+        details["error"] = error;
+    }
+    Some(details)
+}
+
+/// Send an operation to the agent, recording changes in the audit trail
+async fn execute(
+    device_manager: &DeviceManager,
+    user: &AuthUser,
+    session: &Session,
+    context: &RequestContext,
+    operation: serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let result = device_manager.send_registry_command(session.id, operation.clone()).await;
+
+    if let Some(details) = change_details(&operation, &result) {
+        let action = operation.get("op").and_then(|v| v.as_str()).unwrap_or("change");
+        device_manager.record_audit(
+            AuditLog::new("registry", action)
+                .actor(user.user_id.to_string())
+                .agent(session.agent_id)
+                .session(session.id)
+                .request(context)
+                .details(details),
+        ).await;
+    }
+    result
+}
+
+/// Send an operation to the agent and translate its result into a response
+async fn run_operation(
+    app_state: &AppState,
+    user: &AuthUser,
+    session: &Session,
+    context: &RequestContext,
+    elevation_id: Option<Uuid>,
+    operation: serde_json::Value,
+) -> Result<serde_json::Value, Response> {
+    let session_id = session.id;
+    let result = execute(&app_state.device_manager, user, session, context, operation)
+        .await
+        .map_err(|e| error_response(StatusCode::BAD_GATEWAY, e))?;
+
+    let audit = result.get("audit").cloned().unwrap_or(serde_json::Value::Null);
+    if !audit.is_null() {
+        info!("Registry audit for session {} by {}: {}", session_id, user.email, audit);
+    }
+
+    if let Some(elevation_id) = elevation_id {
+        app_state.device_manager.pam_manager.record_elevated_action(
+            elevation_id,
+            session_id,
+            &user.user_id.to_string(),
+            "registry_modified",
+            audit.clone(),
+        ).await;
+    }
+
+    if result.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
+        Ok(serde_json::json!({
+            "result": result.get("result").cloned().unwrap_or(serde_json::Value::Null),
+            "audit": audit,
+        }))
+    } else {
+        let error = result.get("error").and_then(|v| v.as_str()).unwrap_or("Registry operation failed");
+        warn!("Registry operation failed for session {}: {}", session_id, error);
+        Err(error_response(StatusCode::BAD_REQUEST, error))
+    }
+}
+
+/// Query a key's values and subkeys
+pub async fn api_registry_get_key(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<Uuid>,
+    Query(query): Query<RegistryKeyQuery>,
+) -> Response {
+    let operation = serde_json::json!({ "op": "query", "path": query.path });
+    let result = async {
+        let (session, elevation) = authorize(&app_state, &user, session_id, &query.path, false).await?;
+        run_operation(&app_state, &user, &session, &context, elevation, operation).await
+    }.await;
+
+    match result {
+        Ok(body) => Json(body["result"].clone()).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Create a key
+pub async fn api_registry_create_key(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<Uuid>,
+    Json(request): Json<CreateKeyRequest>,
+) -> Response {
+    let operation = serde_json::json!({ "op": "create_key", "path": request.path });
+    let result = async {
+        let (session, elevation) = authorize(&app_state, &user, session_id, &request.path, true).await?;
+        run_operation(&app_state, &user, &session, &context, elevation, operation).await
+    }.await;
+
+    match result {
+        Ok(body) => (StatusCode::CREATED, Json(body)).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Delete a key and all of its subkeys
+pub async fn api_registry_delete_key(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<Uuid>,
+    Query(query): Query<RegistryKeyQuery>,
+) -> Response {
+    let operation = serde_json::json!({ "op": "delete_key", "path": query.path });
+    let result = async {
+        let (session, elevation) = authorize(&app_state, &user, session_id, &query.path, true).await?;
+        run_operation(&app_state, &user, &session, &context, elevation, operation).await
+    }.await;
+
+    match result {
+        Ok(body) => Json(body).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Create or overwrite a value
+pub async fn api_registry_set_value(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<Uuid>,
+    Json(request): Json<SetValueRequest>,
+) -> Response {
+    let operation = serde_json::json!({
+        "op": "set_value",
+        "path": request.path,
+        "name": request.name,
+        "value": request.value,
+    });
+    let result = async {
+        let (session, elevation) = authorize(&app_state, &user, session_id, &request.path, true).await?;
+        run_operation(&app_state, &user, &session, &context, elevation, operation).await
+    }.await;
+
+    match result {
+        Ok(body) => Json(body).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Delete a value
+pub async fn api_registry_delete_value(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<Uuid>,
+    Query(query): Query<RegistryValueQuery>,
+) -> Response {
+    let operation = serde_json::json!({ "op": "delete_value", "path": query.path, "name": query.name });
+    let result = async {
+        let (session, elevation) = authorize(&app_state, &user, session_id, &query.path, true).await?;
+        run_operation(&app_state, &user, &session, &context, elevation, operation).await
+    }.await;
+
+    match result {
+        Ok(body) => Json(body).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Export a key and its subkeys as a .reg file
+pub async fn api_registry_export(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<Uuid>,
+    Query(query): Query<RegistryKeyQuery>,
+) -> Response {
+    let operation = serde_json::json!({ "op": "export", "path": query.path });
+    let result = async {
+        let (session, elevation) = authorize(&app_state, &user, session_id, &query.path, false).await?;
+        run_operation(&app_state, &user, &session, &context, elevation, operation).await
+    }.await;
+
+    let body = match result {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let text = body["result"].as_str().unwrap_or_default().to_string();
+    let filename = format!("attachment; filename=\"session-{}.reg\"", session_id);
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, filename),
+        ],
+        text,
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{AuditFile, AuditFileConfig, AuditFilter};
+    use crate::device_manager::{DeviceRegistration, SessionRequest};
+    use crate::models::SessionType;
+    use crate::relay::outbound;
+    use axum::extract::ws::Message;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_changes_are_audited_with_before_and_after() {
+        let dir = std::env::temp_dir().join(format!("ghostlink-registry-{}", Uuid::new_v4()));
+        let device_manager = DeviceManager::new()
+            .with_audit_file(Arc::new(AuditFile::new(&AuditFileConfig { dir: dir.clone(), ..Default::default() })));
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "till-01".to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = device_manager.register_device(registration, agent_tx).await.unwrap();
+        let user = AuthUser {
+            user_id: Uuid::new_v4(),
+            email: "tech@example.com".to_string(),
+            role: "technician".to_string(),
+            org_id: None,
+            orgs: Vec::new(),
+        };
+        let request = SessionRequest { agent_id, session_type: SessionType::Control, user_id: user.user_id, capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = device_manager.create_session(request, outbound::channel().0).await.unwrap();
+        let session = device_manager.get_session(session_id).await.unwrap();
+        let context = RequestContext { source_ip: Some("203.0.113.9".to_string()), user_agent: None };
+
+        // The agent answers with its own audit entry
+        let agent = async {
+            loop {
+                let Some(Message::Text(text)) = agent_rx.recv().await else { continue };
+                let request: serde_json::Value = serde_json::from_str(&text).unwrap();
+                if request["type"] != "RegistryRequest" {
+                    continue;
+                }
+                let request_id = Uuid::parse_str(request["request_id"].as_str().unwrap()).unwrap();
+                device_manager.complete_registry_request(request_id, serde_json::json!({
+                    "type": "RegistryResult",
+                    "success": true,
+                    "result": null,
+                    "audit": {
+                        "operation": "set_value",
+                        "before": "\"Level\"=dword:00000001",
+                        "after": "\"Level\"=dword:0000000a",
+                    },
+                })).await;
+                break;
+            }
+        };
+        let operation = serde_json::json!({
+            "op": "set_value",
+            "path": "HKCU\\Software\\GhostLink",
+            "name": "Level",
+            "value": 10,
+        });
+        let (result, ()) = tokio::join!(execute(&device_manager, &user, &session, &context, operation), agent);
+        assert_eq!(result.unwrap()["success"], true);
+
+        let filter = AuditFilter { category: Some("registry".to_string()), ..Default::default() };
+        let (entries, total) = device_manager.query_audit(&filter, 10, 0).await.unwrap();
+        assert_eq!(total, 1);
+        let entry = &entries[0];
+        assert_eq!((entry.category.as_str(), entry.action.as_str()), ("registry", "set_value"));
+        assert_eq!(entry.actor.as_deref(), Some(user.user_id.to_string().as_str()));
+        assert_eq!((entry.session_id, entry.agent_id), (Some(session_id), Some(agent_id)));
+        assert_eq!(entry.source_ip.as_deref(), Some("203.0.113.9"));
+        assert_eq!(entry.details.0, serde_json::json!({
+            "operation": "set_value",
+            "path": "HKCU\\Software\\GhostLink",
+            "name": "Level",
+            "before": "\"Level\"=dword:00000001",
+            "after": "\"Level\"=dword:0000000a",
+            "success": true,
+        }));
+
+        // Reads are not audited, and an agent that never answered may still have changed it
+        let query = serde_json::json!({ "op": "query", "path": "HKCU\\Software" });
+        assert!(change_details(&query, &Ok(serde_json::Value::Null)).is_none());
+        let delete = serde_json::json!({ "op": "delete_key", "path": "HKCU\\Software\\GhostLink" });
+        let details = change_details(&delete, &Err("Agent did not answer".to_string())).unwrap();
+        assert_eq!((details["success"].clone(), details["before"].clone()), (serde_json::json!(false), serde_json::Value::Null));
+        assert_eq!(details["error"], "Agent did not answer");
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+//! Per-session bandwidth caps
+//!
+//! A technician can cap how much a session's screen stream may use, either
+//! with `bandwidth_kbps` when creating the session or live with a
+//! `set_bandwidth` command. The relay holds the cap to the ceiling of the
+//! agent's effective policy (`max_bandwidth_kbps`), which applies even to
+//! sessions that asked for no cap, keeps the result in the session's
+//! settings and hands it to the agent. The agent enforces it: its adaptive
+//! controller lowers frame rate and bitrate to fit, and a token bucket in
+//! front of the socket catches what the encoder overshoots. The quality
+//! report carries the measured throughput next to the cap.
+
+/// Settings key of the session's bandwidth cap in kbit/s; absent means none
+pub const BANDWIDTH_CAP_SETTING: &str = "bandwidth_kbps";
+
+/// Caps a session or policy may set, in kbit/s. Below the minimum not even
+/// a grayscale frame a second gets through.
+pub const BANDWIDTH_RANGE: std::ops::RangeInclusive<u32> = 64..=1_000_000;
+
+/// Reject caps outside [`BANDWIDTH_RANGE`]
+pub fn validate_cap(kbps: u32) -> Result<u32, String> {
+    if BANDWIDTH_RANGE.contains(&kbps) {
+        Ok(kbps)
+    } else {
+        Err(format!(
+            "Bandwidth cap must be between {} and {} kbit/s",
+            BANDWIDTH_RANGE.start(),
+            BANDWIDTH_RANGE.end()
+        ))
+    }
+}
+
+/// The cap in force: the lower of the one asked for and the policy ceiling
+pub fn effective_cap(requested: Option<u32>, ceiling: Option<u32>) -> Option<u32> {
+    match (requested, ceiling) {
+        (Some(requested), Some(ceiling)) => Some(requested.min(ceiling)),
+        (cap, None) | (None, cap) => cap,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ceiling_bounds_the_cap() {
+        assert_eq!(effective_cap(None, None), None);
+        assert_eq!(effective_cap(Some(2000), None), Some(2000));
+        assert_eq!(effective_cap(Some(2000), Some(500)), Some(500));
+        assert_eq!(effective_cap(Some(300), Some(500)), Some(300));
+        // Sessions without a cap of their own still get the ceiling
+        assert_eq!(effective_cap(None, Some(500)), Some(500));
+
+        assert!(validate_cap(63).is_err());
+        assert_eq!(validate_cap(256), Ok(256));
+    }
+}
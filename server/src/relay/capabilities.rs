@@ -0,0 +1,233 @@
+//! What an agent can do, and which sessions that allows
+//!
+//! Agents list their capabilities in `AgentRegister`, judged by what
+//! initialized on the machine rather than what was compiled in. A session
+//! needing something the agent lacks is refused with a 409 and a reason the
+//! web UI can show, instead of starting and failing silently on the agent.
+//! Agents that never sent a list are not held back.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeSet;
+
+use crate::auth::session_tokens::SessionScope;
+use crate::models::SessionType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentCapability {
+    ScreenCapture,
+    InputControl,
+    FileTransfer,
+    Terminal,
+    Clipboard,
+    AudioCapture,
+    MultiMonitor,
+    /// Records sessions on the device; older agents call it `session_recording`
+    #[serde(alias = "session_recording")]
+    Recording,
+    Elevation,
+}
+
+impl AgentCapability {
+    /// What the capability is, for messages to technicians
+    pub fn label(self) -> &'static str {
+        match self {
+            AgentCapability::ScreenCapture => "screen capture",
+            AgentCapability::InputControl => "remote control",
+            AgentCapability::FileTransfer => "file transfer",
+            AgentCapability::Terminal => "terminals",
+            AgentCapability::Clipboard => "clipboard sharing",
+            AgentCapability::AudioCapture => "audio",
+            AgentCapability::MultiMonitor => "multiple monitors",
+            AgentCapability::Recording => "session recording",
+            AgentCapability::Elevation => "elevation",
+        }
+    }
+
+    /// Capability a session scope exercises; viewing also covers chat and
+    /// stream settings, so it needs none of its own
+    pub fn for_scope(scope: SessionScope) -> Option<Self> {
+        match scope {
+            SessionScope::View => None,
+            SessionScope::Control => Some(AgentCapability::InputControl),
+            SessionScope::File => Some(AgentCapability::FileTransfer),
+            SessionScope::Terminal => Some(AgentCapability::Terminal),
+        }
+    }
+
+    /// Capabilities a session of `session_type` cannot start without
+    pub fn required_for(session_type: &SessionType) -> &'static [AgentCapability] {
+        match session_type {
+            SessionType::View => &[AgentCapability::ScreenCapture],
+            SessionType::Control | SessionType::Console | SessionType::Adhoc => {
+                &[AgentCapability::ScreenCapture, AgentCapability::InputControl]
+            }
+            SessionType::FileTransfer => &[AgentCapability::FileTransfer],
+            // Backstage works in the device's shell, away from its screen
+            SessionType::Backstage => &[AgentCapability::Terminal],
+        }
+    }
+}
+
+/// The capabilities an agent reported; unset for agents that sent no list,
+/// which are taken to support everything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct AgentCapabilities(Option<BTreeSet<AgentCapability>>);
+
+impl AgentCapabilities {
+    pub fn reported(capabilities: impl IntoIterator<Item = AgentCapability>) -> Self {
+        Self(Some(capabilities.into_iter().collect()))
+    }
+
+    pub fn supports(&self, capability: AgentCapability) -> bool {
+        self.0.as_ref().is_none_or(|reported| reported.contains(&capability))
+    }
+
+    /// Refuse a session needing any of `required` the agent lacks
+    pub fn check(&self, required: &[AgentCapability]) -> Result<(), Unsupported> {
+        let missing: Vec<_> = required.iter().copied().filter(|&capability| !self.supports(capability)).collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let view_only = missing.contains(&AgentCapability::InputControl) && self.supports(AgentCapability::ScreenCapture);
+        Err(Unsupported { missing, view_only })
+    }
+
+    /// `scopes` without those the agent cannot serve
+    pub fn usable_scopes(&self, scopes: Vec<SessionScope>) -> Vec<SessionScope> {
+        scopes
+            .into_iter()
+            .filter(|&scope| AgentCapability::for_scope(scope).is_none_or(|capability| self.supports(capability)))
+            .collect()
+    }
+}
+
+impl<'de> Deserialize<'de> for AgentCapabilities {
+    /// Names from newer agents are skipped. Devices stored before agents
+    /// reported a list hold an empty object, which reads as no list.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let reported = match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Array(names) => Some(
+                names
+                    .into_iter()
+                    .filter_map(|name| serde_json::from_value(name).ok())
+                    .collect(),
+            ),
+            _ => None,
+        };
+        Ok(Self(reported))
+    }
+}
+
+/// A session the agent cannot serve
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    pub missing: Vec<AgentCapability>,
+    /// The device can still be watched
+    pub view_only: bool,
+}
+
+impl Unsupported {
+    /// Code the web UI keys on
+    pub fn reason(&self) -> &'static str {
+        if self.view_only {
+            "view_only"
+        } else {
+            "missing_capability"
+        }
+    }
+}
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.view_only {
+            return write!(f, "This device supports view-only access");
+        }
+        let labels: Vec<_> = self.missing.iter().map(|capability| capability.label()).collect();
+        write!(f, "This device does not support {}", labels.join(" or "))
+    }
+}
+
+impl IntoResponse for Unsupported {
+    fn into_response(self) -> Response {
+        (StatusCode::CONFLICT, Json(serde_json::json!({
+            "error": self.to_string(),
+            "reason": self.reason(),
+            "missing_capabilities": self.missing,
+        }))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AgentCapability::*;
+
+    fn agent(capabilities: &[AgentCapability]) -> AgentCapabilities {
+        AgentCapabilities::reported(capabilities.iter().copied())
+    }
+
+    fn allows(capabilities: &AgentCapabilities, session_type: SessionType) -> Result<(), Unsupported> {
+        capabilities.check(AgentCapability::required_for(&session_type))
+    }
+
+    #[test]
+    fn test_registration_lists_are_read_leniently() {
+        let older: AgentCapabilities = serde_json::from_value(serde_json::json!([
+            "screen_capture", "input_control", "session_recording", "high_fps_capture", "terminal"
+        ])).unwrap();
+        assert_eq!(older, agent(&[ScreenCapture, InputControl, Recording, Terminal]));
+        assert_eq!(serde_json::to_value(&older).unwrap(), serde_json::json!([
+            "screen_capture", "input_control", "terminal", "recording"
+        ]));
+
+        // Devices stored before the list read as agents that sent none
+        let stored: AgentCapabilities = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(stored, AgentCapabilities::default());
+        assert_eq!(serde_json::to_value(&stored).unwrap(), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_sessions_need_what_they_use() {
+        let full = agent(&[ScreenCapture, InputControl, FileTransfer, Terminal, Clipboard]);
+        let view_only = agent(&[ScreenCapture, FileTransfer]);
+        let headless = agent(&[FileTransfer, Terminal, Elevation]);
+        let unreported = AgentCapabilities::default();
+
+        for session_type in [SessionType::View, SessionType::Control, SessionType::Adhoc, SessionType::FileTransfer, SessionType::Backstage] {
+            assert_eq!(allows(&full, session_type.clone()), Ok(()), "{}", session_type);
+            assert_eq!(allows(&unreported, session_type.clone()), Ok(()), "{}", session_type);
+        }
+
+        assert_eq!(allows(&view_only, SessionType::View), Ok(()));
+        assert_eq!(allows(&view_only, SessionType::FileTransfer), Ok(()));
+        let refused = allows(&view_only, SessionType::Control).unwrap_err();
+        assert_eq!(refused, Unsupported { missing: vec![InputControl], view_only: true });
+        assert_eq!(refused.reason(), "view_only");
+        assert_eq!(refused.to_string(), "This device supports view-only access");
+        assert!(allows(&view_only, SessionType::Backstage).is_err());
+
+        assert_eq!(allows(&headless, SessionType::Backstage), Ok(()));
+        assert_eq!(allows(&headless, SessionType::FileTransfer), Ok(()));
+        let refused = allows(&headless, SessionType::Console).unwrap_err();
+        assert_eq!(refused.missing, [ScreenCapture, InputControl]);
+        assert_eq!(refused.reason(), "missing_capability");
+        assert_eq!(refused.to_string(), "This device does not support screen capture or remote control");
+    }
+
+    #[test]
+    fn test_default_scopes_narrow_to_the_agent() {
+        let all = vec![SessionScope::View, SessionScope::Control, SessionScope::File, SessionScope::Terminal];
+        let no_terminal = agent(&[ScreenCapture, InputControl, FileTransfer]);
+        assert_eq!(no_terminal.usable_scopes(all.clone()), [SessionScope::View, SessionScope::Control, SessionScope::File]);
+        assert_eq!(AgentCapabilities::default().usable_scopes(all.clone()), all);
+        // Viewing needs nothing of its own, so file-only agents still chat
+        assert_eq!(agent(&[FileTransfer]).usable_scopes(all), [SessionScope::View, SessionScope::File]);
+    }
+}
@@ -0,0 +1,426 @@
+//! In-session chat between the technician and the end user
+//!
+//! Chat travels as `ChatMessage` on the session's WebSocket and the agent's.
+//! The relay checks and stamps every message, keeps it on the session
+//! timeline as the transcript, and answers the sender with a `sent`
+//! receipt. The other side acknowledges with `ChatReceipt`s once the message
+//! reached the end user's chat window (or the viewer) and once it was read;
+//! the relay forwards a receipt only when it moves the message forward, so a
+//! late or repeated one never turns a read message back into a delivered one.
+//!
+//! Bodies bound for the web (viewer frames and the transcript) are
+//! HTML-escaped here; the agent gets the text as typed.
+
+use axum::extract::ws::Message;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::device_manager::DeviceManager;
+use crate::session_events::{SessionEvent, SessionEventKind};
+
+/// Longest message body, in characters
+pub const MAX_CHAT_BODY: usize = 4000;
+
+/// Longest sender name, in characters
+const MAX_SENDER_NAME: usize = 100;
+
+/// Messages per session whose receipts are tracked; older ones are forgotten
+const TRACKED_MESSAGES: usize = 500;
+
+/// Who wrote a chat message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatParty {
+    Technician,
+    EndUser,
+}
+
+impl ChatParty {
+    fn default_name(self) -> &'static str {
+        match self {
+            ChatParty::Technician => "Technician",
+            ChatParty::EndUser => "End user",
+        }
+    }
+}
+
+/// How far a message got; only ever moves forward
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    /// Accepted by the relay
+    Sent,
+    /// Shown, or queued until the recipient's chat window opens
+    Delivered,
+    Read,
+}
+
+impl ReceiptStatus {
+    /// Move to `next` if that is further along; returns whether it moved
+    pub fn advance(&mut self, next: ReceiptStatus) -> bool {
+        if next > *self {
+            *self = next;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Trim `body` and drop control characters other than newlines and tabs.
+///
+/// Fails on bodies that are empty afterwards or longer than [`MAX_CHAT_BODY`].
+pub fn sanitize_body(body: &str) -> Result<String, &'static str> {
+    let body: String = body.chars().filter(|c| !c.is_control() || matches!(c, '\n' | '\t')).collect();
+    let body = body.trim().to_string();
+    if body.is_empty() {
+        return Err("Message is empty");
+    }
+    if body.chars().count() > MAX_CHAT_BODY {
+        return Err("Message is too long");
+    }
+    Ok(body)
+}
+
+/// Escape `text` for use in HTML content or attribute values
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug)]
+struct TrackedMessage {
+    id: Uuid,
+    from: ChatParty,
+    status: ReceiptStatus,
+}
+
+/// Receipt state of the recent messages of every session
+#[derive(Debug, Default)]
+pub struct ChatTracker {
+    sessions: RwLock<HashMap<Uuid, VecDeque<TrackedMessage>>>,
+}
+
+impl ChatTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking a message the relay accepted; false if it already was
+    pub async fn sent(&self, session_id: Uuid, message_id: Uuid, from: ChatParty) -> bool {
+        let mut sessions = self.sessions.write().await;
+        let messages = sessions.entry(session_id).or_default();
+        if messages.iter().any(|message| message.id == message_id) {
+            return false;
+        }
+        if messages.len() >= TRACKED_MESSAGES {
+            messages.pop_front();
+        }
+        messages.push_back(TrackedMessage { id: message_id, from, status: ReceiptStatus::Sent });
+        true
+    }
+
+    /// Apply a receipt `by` one side for a message of the other side.
+    ///
+    /// Returns the message's author if the receipt moved it forward, which
+    /// is who it should be forwarded to.
+    pub async fn receipt(&self, session_id: Uuid, message_id: Uuid, by: ChatParty, status: ReceiptStatus) -> Option<ChatParty> {
+        let mut sessions = self.sessions.write().await;
+        let message = sessions.get_mut(&session_id)?.iter_mut().find(|message| message.id == message_id)?;
+        if message.from == by || !message.status.advance(status) {
+            return None;
+        }
+        Some(message.from)
+    }
+
+    /// Current status of a message
+    pub async fn status(&self, session_id: Uuid, message_id: Uuid) -> Option<ReceiptStatus> {
+        let sessions = self.sessions.read().await;
+        sessions.get(&session_id)?.iter().find(|message| message.id == message_id).map(|message| message.status)
+    }
+
+    pub async fn remove(&self, session_id: Uuid) {
+        self.sessions.write().await.remove(&session_id);
+    }
+}
+
+/// Send `message` to `party` of a session
+async fn send_to(device_manager: &Arc<DeviceManager>, session_id: Uuid, party: ChatParty, message: serde_json::Value) -> Result<(), String> {
+    let message = Message::Text(message.to_string());
+    match party {
+        ChatParty::Technician => device_manager.send_to_session(session_id, message).await,
+        ChatParty::EndUser => device_manager.send_to_session_agent(session_id, message).await,
+    }
+}
+
+fn other(party: ChatParty) -> ChatParty {
+    match party {
+        ChatParty::Technician => ChatParty::EndUser,
+        ChatParty::EndUser => ChatParty::Technician,
+    }
+}
+
+/// Tell `party` that its message could not be relayed
+async fn reject(device_manager: &Arc<DeviceManager>, session_id: Uuid, party: ChatParty, message_id: Uuid, error: &str) {
+    let reply = serde_json::json!({
+        "type": "ChatError",
+        "session_id": session_id,
+        "message_id": message_id,
+        "error": error,
+    });
+    if let Err(e) = send_to(device_manager, session_id, party, reply).await {
+        debug!("Failed to reject chat message of session {}: {}", session_id, e);
+    }
+}
+
+/// Relay a `ChatMessage` written by `from` to the other side of the session
+pub async fn relay_message(device_manager: &Arc<DeviceManager>, session_id: Uuid, from: ChatParty, cmd: &serde_json::Value) {
+    let Some(session) = device_manager.get_session(session_id).await else {
+        return;
+    };
+    let message_id = cmd
+        .get("message_id")
+        .and_then(|v| v.as_str())
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .unwrap_or_else(Uuid::new_v4);
+    let body = match sanitize_body(cmd.get("body").and_then(|v| v.as_str()).unwrap_or("")) {
+        Ok(body) => body,
+        Err(error) => {
+            reject(device_manager, session_id, from, message_id, error).await;
+            return;
+        }
+    };
+    if !device_manager.chat_tracker.sent(session_id, message_id, from).await {
+        debug!("Ignoring repeated chat message {} in session {}", message_id, session_id);
+        return;
+    }
+    device_manager.idle_tracker.touch(session_id, Utc::now()).await;
+
+    let sender: String = match cmd.get("sender").and_then(|v| v.as_str()).map(str::trim) {
+        Some(name) if !name.is_empty() => name.chars().filter(|c| !c.is_control()).take(MAX_SENDER_NAME).collect(),
+        _ => from.default_name().to_string(),
+    };
+    let sent_at = Utc::now();
+    let recipient = other(from);
+    let (sender_for_recipient, body_for_recipient) = match recipient {
+        ChatParty::Technician => (escape_html(&sender), escape_html(&body)),
+        ChatParty::EndUser => (sender.clone(), body.clone()),
+    };
+    let message = serde_json::json!({
+        "type": "ChatMessage",
+        "session_id": session_id,
+        "message_id": message_id,
+        "from": from,
+        "sender": sender_for_recipient,
+        "body": body_for_recipient,
+        "sent_at": sent_at,
+    });
+    if let Err(e) = send_to(device_manager, session_id, recipient, message).await {
+        warn!("Failed to relay chat message of session {}: {}", session_id, e);
+        reject(device_manager, session_id, from, message_id, "The other side is not connected").await;
+        return;
+    }
+
+    let receipt = serde_json::json!({
+        "type": "ChatReceipt",
+        "session_id": session_id,
+        "message_id": message_id,
+        "status": ReceiptStatus::Sent,
+        "sent_at": sent_at,
+    });
+    if let Err(e) = send_to(device_manager, session_id, from, receipt).await {
+        debug!("Failed to acknowledge chat message of session {}: {}", session_id, e);
+    }
+
+    // The transcript is part of the session timeline
+    let mut event = SessionEvent::new(
+        session_id,
+        SessionEventKind::Message,
+        escape_html(&sender),
+        serde_json::json!({ "message_id": message_id, "from": from, "body": escape_html(&body) }),
+    );
+    event.id = message_id;
+    event.timestamp = sent_at;
+    device_manager.record_session_events(&session, session.user_id, vec![event]).await;
+}
+
+/// Relay a `ChatReceipt` from `by` to the author of the message it is for
+pub async fn relay_receipt(device_manager: &Arc<DeviceManager>, session_id: Uuid, by: ChatParty, cmd: &serde_json::Value) {
+    let message_id = cmd.get("message_id").and_then(|v| v.as_str()).and_then(|id| Uuid::parse_str(id).ok());
+    let status = cmd.get("status").cloned().and_then(|status| serde_json::from_value::<ReceiptStatus>(status).ok());
+    let (Some(message_id), Some(status)) = (message_id, status) else {
+        debug!("Malformed chat receipt in session {}", session_id);
+        return;
+    };
+    let Some(author) = device_manager.chat_tracker.receipt(session_id, message_id, by, status).await else {
+        return;
+    };
+
+    let receipt = serde_json::json!({
+        "type": "ChatReceipt",
+        "session_id": session_id,
+        "message_id": message_id,
+        "status": status,
+    });
+    if let Err(e) = send_to(device_manager, session_id, author, receipt).await {
+        debug!("Failed to forward chat receipt of session {}: {}", session_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_manager::{DeviceRegistration, SessionRequest};
+    use crate::models::SessionType;
+    use crate::relay::outbound::{self, OutboundReceiver};
+    use std::time::Duration;
+
+    #[test]
+    fn test_receipts_only_move_forward() {
+        let mut status = ReceiptStatus::Sent;
+        assert!(!status.advance(ReceiptStatus::Sent));
+        assert!(status.advance(ReceiptStatus::Read), "delivery can be skipped");
+        assert!(!status.advance(ReceiptStatus::Delivered));
+        assert_eq!(status, ReceiptStatus::Read);
+        assert_eq!(serde_json::to_value(ReceiptStatus::Delivered).unwrap(), "delivered");
+    }
+
+    #[test]
+    fn test_sanitize_and_escape() {
+        assert_eq!(sanitize_body("  hi\u{7}\tthere\r\n ").unwrap(), "hi\tthere");
+        assert_eq!(sanitize_body("one\ntwo").unwrap(), "one\ntwo");
+        assert!(sanitize_body(" \u{0} ").is_err());
+        assert!(sanitize_body(&"x".repeat(MAX_CHAT_BODY)).is_ok());
+        assert!(sanitize_body(&"x".repeat(MAX_CHAT_BODY + 1)).is_err());
+        assert_eq!(escape_html(r#"<b onclick="x">'&'</b>"#), "&lt;b onclick=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/b&gt;");
+    }
+
+    #[tokio::test]
+    async fn test_tracker_checks_who_acknowledges() {
+        let tracker = ChatTracker::new();
+        let (session_id, message_id) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(tracker.sent(session_id, message_id, ChatParty::Technician).await);
+        assert!(!tracker.sent(session_id, message_id, ChatParty::Technician).await);
+
+        // Only the recipient acknowledges, and only forwards
+        assert_eq!(tracker.receipt(session_id, message_id, ChatParty::Technician, ReceiptStatus::Read).await, None);
+        assert_eq!(tracker.receipt(session_id, message_id, ChatParty::EndUser, ReceiptStatus::Delivered).await, Some(ChatParty::Technician));
+        assert_eq!(tracker.receipt(session_id, message_id, ChatParty::EndUser, ReceiptStatus::Delivered).await, None);
+        assert_eq!(tracker.receipt(Uuid::new_v4(), message_id, ChatParty::EndUser, ReceiptStatus::Read).await, None);
+        assert_eq!(tracker.status(session_id, message_id).await, Some(ReceiptStatus::Delivered));
+
+        tracker.remove(session_id).await;
+        assert_eq!(tracker.status(session_id, message_id).await, None);
+    }
+
+    async fn next_text(rx: &OutboundReceiver, kind: &str) -> serde_json::Value {
+        loop {
+            match tokio::time::timeout(Duration::from_millis(200), rx.recv()).await {
+                Ok(Some(Message::Text(text))) => {
+                    let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    if message["type"] == kind {
+                        return message;
+                    }
+                }
+                other => panic!("no {} arrived: {:?}", kind, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chat_is_relayed_both_ways() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-01".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = device_manager.register_device(registration, agent_tx).await.unwrap();
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        let request = SessionRequest { agent_id, session_type: SessionType::Control, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = device_manager.create_session(request, viewer_tx).await.unwrap();
+        let agent = agent_id.to_string();
+
+        // Technician to end user, who gets the text as typed
+        let message_id = Uuid::new_v4();
+        let chat = serde_json::json!({
+            "type": "ChatMessage",
+            "session_id": Uuid::new_v4(),
+            "message_id": message_id,
+            "sender": "Sam",
+            "body": "  Can you open <Settings>? ",
+        });
+        super::super::handle_session_command(&device_manager, &session_id.to_string(), chat.clone()).await.unwrap();
+        let delivered = next_text(&agent_rx, "ChatMessage").await;
+        assert_eq!(delivered["session_id"], session_id.to_string(), "the session is pinned");
+        assert_eq!(delivered["body"], "Can you open <Settings>?");
+        assert_eq!((delivered["from"].as_str(), delivered["sender"].as_str()), (Some("technician"), Some("Sam")));
+        assert_eq!(next_text(&viewer_rx, "ChatReceipt").await["status"], "sent");
+        let transcript = next_text(&viewer_rx, "SessionEvent").await;
+        assert_eq!(transcript["event"]["id"], message_id.to_string());
+        assert_eq!(transcript["event"]["details"]["body"], "Can you open &lt;Settings&gt;?");
+
+        // A repeat is not delivered twice
+        super::super::handle_session_command(&device_manager, &session_id.to_string(), chat).await.unwrap();
+
+        // Receipts reach the technician once each, in order only
+        for status in ["delivered", "delivered", "read", "delivered"] {
+            let receipt = serde_json::json!({
+                "type": "ChatReceipt",
+                "session_id": session_id,
+                "message_id": message_id,
+                "status": status,
+            });
+            super::super::handle_agent_command(&device_manager, &agent, receipt).await.unwrap();
+        }
+        assert_eq!(next_text(&viewer_rx, "ChatReceipt").await["status"], "delivered");
+        assert_eq!(next_text(&viewer_rx, "ChatReceipt").await["status"], "read");
+
+        // End user to technician, escaped for the viewer
+        let reply = serde_json::json!({
+            "type": "ChatMessage",
+            "session_id": session_id,
+            "body": "<script>alert(1)</script>",
+        });
+        super::super::handle_agent_command(&device_manager, &agent, reply).await.unwrap();
+        let shown = next_text(&viewer_rx, "ChatMessage").await;
+        assert_eq!(shown["body"], "&lt;script&gt;alert(1)&lt;/script&gt;");
+        assert_eq!((shown["from"].as_str(), shown["sender"].as_str()), (Some("end_user"), Some("End user")));
+        let sent = next_text(&agent_rx, "ChatReceipt").await;
+        assert_eq!(sent["message_id"], shown["message_id"]);
+
+        // Invalid messages bounce back to their author
+        let empty = serde_json::json!({ "type": "ChatMessage", "body": " " });
+        super::super::handle_session_command(&device_manager, &session_id.to_string(), empty).await.unwrap();
+        assert_eq!(next_text(&viewer_rx, "ChatError").await["error"], "Message is empty");
+        let nothing = tokio::time::timeout(Duration::from_millis(50), agent_rx.recv()).await;
+        assert!(nothing.is_err(), "the agent got {:?}", nothing);
+
+        let events = device_manager.session_events(session_id, None).await;
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| event.kind == SessionEventKind::Message));
+    }
+}
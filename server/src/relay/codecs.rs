@@ -0,0 +1,86 @@
+//! Codec negotiation between viewers and agents
+//!
+//! A viewer announces what it decodes when it asks for a session. The relay
+//! checks the announcement, keeps it in the session's settings and hands it
+//! to the agent with the `SessionRequest`. The agent answers with a
+//! `StreamConfig` naming the codec before its first frame, which the relay
+//! passes on to the viewer. A viewer that cannot decode the stream sends
+//! `decode_failed`, and the agent is asked to step down with
+//! `StreamDowngrade` instead of the session ending.
+
+use serde::{Deserialize, Serialize};
+
+/// Codecs agents can stream, as viewers name them
+pub const CODECS: [&str; 5] = ["av1", "h265", "h264", "jpeg", "png"];
+
+/// H.264 profiles a viewer may restrict the stream to
+pub const H264_PROFILES: [&str; 3] = ["baseline", "main", "high"];
+
+/// Settings key the announced capabilities are kept under
+pub const CAPABILITIES_SETTING: &str = "viewer_capabilities";
+
+/// Settings key the agent's confirmed stream config is kept under
+pub const STREAM_CONFIG_SETTING: &str = "stream_config";
+
+/// What a viewer can decode
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ViewerCapabilities {
+    pub codecs: Vec<String>,
+    /// H.264 profiles the viewer decodes; empty means all of them
+    pub h264_profiles: Vec<String>,
+    /// Largest video frame the viewer's decoder takes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    /// Whether the viewer paints tile frames, the changed regions of the
+    /// screen, over its current picture
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub tiles: bool,
+}
+
+impl ViewerCapabilities {
+    /// Reject announcements no agent could stream to
+    pub fn validate(&self) -> Result<(), String> {
+        if self.codecs.is_empty() {
+            return Err("Viewer capabilities list no codecs".to_string());
+        }
+        if let Some(codec) = self.codecs.iter().find(|codec| !CODECS.contains(&codec.as_str())) {
+            return Err(format!("Unknown codec: {}", codec));
+        }
+        if let Some(profile) = self.h264_profiles.iter().find(|profile| !H264_PROFILES.contains(&profile.as_str())) {
+            return Err(format!("Unknown H.264 profile: {}", profile));
+        }
+        if self.max_width == Some(0) || self.max_height == Some(0) {
+            return Err("Maximum resolution must not be zero".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_are_validated() {
+        let capabilities: ViewerCapabilities =
+            serde_json::from_value(serde_json::json!({ "codecs": ["h264", "jpeg"], "max_width": 1920 })).unwrap();
+        assert_eq!(capabilities.validate(), Ok(()));
+        assert_eq!(capabilities.max_height, None);
+        assert!(!capabilities.tiles);
+
+        let none = ViewerCapabilities::default();
+        assert_eq!(none.validate(), Err("Viewer capabilities list no codecs".to_string()));
+
+        let unknown = ViewerCapabilities { codecs: vec!["vp9".to_string()], ..ViewerCapabilities::default() };
+        assert_eq!(unknown.validate(), Err("Unknown codec: vp9".to_string()));
+
+        let profile = ViewerCapabilities { h264_profiles: vec!["extended".to_string()], ..capabilities.clone() };
+        assert!(profile.validate().unwrap_err().contains("extended"));
+
+        let zero = ViewerCapabilities { max_height: Some(0), ..capabilities };
+        assert!(zero.validate().is_err());
+    }
+}
@@ -1,89 +1,413 @@
-//! Connection broker for managing P2P and relay connections
+//! Brokering sessions onto relay nodes
 //!
-//! This module handles the brokering of connections between agents and technicians,
-//! deciding whether to use P2P or relay based on network conditions.
+//! Relay nodes register with the server and then heartbeat their load and
+//! health. Each new session is placed on a node by the load balancer, and
+//! both ends get the node's address together with a routing token signed
+//! with the secret the nodes share with the server; a node only relays
+//! sessions whose token names it. A node that misses heartbeats is drained,
+//! keeping its sessions but getting no new ones, and evicted if it stays
+//! silent. Without a node secret configured, or with no node available,
+//! sessions are relayed through this server as before.
 
-#![allow(dead_code)]
-
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use std::collections::HashMap;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::info;
 use uuid::Uuid;
-use tracing::{debug, info};
 
-/// Connection broker for managing session connections
-pub struct ConnectionBroker {
-    /// Active connection requests
-    pending_requests: Arc<RwLock<HashMap<Uuid, ConnectionRequest>>>,
-    /// Established connections
-    active_connections: Arc<RwLock<HashMap<Uuid, ActiveConnection>>>,
+use super::load_balancer::BalancingStrategy;
+use crate::auth::jwt::error;
+use crate::AppState;
+
+/// How often relay nodes are checked for missed heartbeats
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// `purpose` claim that distinguishes routing tokens from other tokens
+const ROUTE_TOKEN_PURPOSE: &str = "relay-route";
+
+/// Largest capacity a node may announce
+const MAX_NODE_CAPACITY: u32 = 100_000;
+
+/// How sessions are spread across relay nodes and when silent nodes go
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrokerPolicy {
+    /// Shared with the relay nodes: they present it to register and verify
+    /// routing tokens with it. Brokering is off while unset.
+    pub node_secret: Option<String>,
+    pub strategy: BalancingStrategy,
+    /// Seconds without a heartbeat before a node gets no new sessions
+    pub drain_after_secs: u64,
+    /// Seconds without a heartbeat before a node is forgotten
+    pub evict_after_secs: u64,
+    /// Lifetime of a routing token; both ends must reach the node within it
+    pub token_ttl_secs: u64,
 }
 
-#[derive(Debug, Clone)]
-pub struct ConnectionRequest {
-    pub session_id: Uuid,
+impl Default for BrokerPolicy {
+    fn default() -> Self {
+        Self {
+            node_secret: None,
+            strategy: BalancingStrategy::default(),
+            drain_after_secs: 30,
+            evict_after_secs: 120,
+            token_ttl_secs: 300,
+        }
+    }
+}
+
+/// Whether a node takes new sessions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeStatus {
+    #[default]
+    Active,
+    /// Missed heartbeats; keeps its sessions but gets no new ones
+    Draining,
+}
+
+/// Body of `POST /api/relay-nodes/register`
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeRegistration {
+    /// Chosen by the node and kept across restarts
+    pub id: Uuid,
+    /// Where agents and technicians reach the node
+    pub address: SocketAddr,
+    pub region: String,
+    /// Sessions the node can carry at once
+    pub capacity: u32,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// Body of `POST /api/relay-nodes/heartbeat`
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeHeartbeat {
+    pub id: Uuid,
+    pub current_load: u32,
+    /// 0.0 (failing) to 1.0 (healthy), as the node judges itself
+    pub health_score: f32,
+    /// Capacity, when it changed since registering
+    #[serde(default)]
+    pub capacity: Option<u32>,
+}
+
+/// What a routing token vouches for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteClaims {
+    pub purpose: String,
+    pub session_id: String,
+    /// The only node that may relay the session
+    pub node_id: Uuid,
     pub agent_id: String,
     pub technician_id: String,
-    pub preferred_type: ConnectionPreference,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokerError {
+    /// No node secret is configured, so nodes cannot register
+    Disabled,
+    /// The node secret was missing or wrong
+    Unauthorized,
+    /// Not registered, or evicted since; the node should register again
+    UnknownNode(Uuid),
+    InvalidNode(String),
+    InvalidToken,
+    TokenExpired,
+    /// The token was issued for a session on another node
+    WrongNode,
 }
 
-#[derive(Debug, Clone)]
-pub enum ConnectionPreference {
-    P2PPreferred,
-    RelayPreferred,
-    P2POnly,
-    RelayOnly,
+impl std::fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrokerError::Disabled => write!(f, "relay node brokering is not configured"),
+            BrokerError::Unauthorized => write!(f, "invalid relay node secret"),
+            BrokerError::UnknownNode(id) => write!(f, "relay node {} is not registered", id),
+            BrokerError::InvalidNode(reason) => write!(f, "invalid relay node: {}", reason),
+            BrokerError::InvalidToken => write!(f, "invalid routing token"),
+            BrokerError::TokenExpired => write!(f, "routing token expired"),
+            BrokerError::WrongNode => write!(f, "routing token was issued for another relay node"),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct ActiveConnection {
-    pub session_id: Uuid,
-    pub connection_type: EstablishedConnectionType,
+impl IntoResponse for BrokerError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            BrokerError::Disabled => StatusCode::SERVICE_UNAVAILABLE,
+            BrokerError::Unauthorized | BrokerError::InvalidToken | BrokerError::TokenExpired => StatusCode::UNAUTHORIZED,
+            BrokerError::WrongNode => StatusCode::FORBIDDEN,
+            BrokerError::UnknownNode(_) => StatusCode::NOT_FOUND,
+            BrokerError::InvalidNode(_) => StatusCode::BAD_REQUEST,
+        };
+        error(status, self.to_string())
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum EstablishedConnectionType {
-    DirectP2P { local_addr: String, remote_addr: String },
-    Relayed { relay_node: String },
-    Hybrid { p2p_addr: Option<String>, relay_node: String },
+/// The secret shared with relay nodes
+pub struct NodeKey {
+    /// Presented secrets are compared by digest so the comparison leaks nothing
+    secret_digest: Vec<u8>,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
 }
 
-impl ConnectionBroker {
-    pub fn new() -> Self {
-        info!("Initializing connection broker");
+impl NodeKey {
+    pub fn new(secret: &str) -> Self {
         Self {
-            pending_requests: Arc::new(RwLock::new(HashMap::new())),
-            active_connections: Arc::new(RwLock::new(HashMap::new())),
+            secret_digest: digest(&SHA256, secret.as_bytes()).as_ref().to_vec(),
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+        }
+    }
+
+    /// Check the secret a node presented
+    pub fn authenticate(&self, presented: Option<&str>) -> Result<(), BrokerError> {
+        match presented {
+            Some(secret) if digest(&SHA256, secret.as_bytes()).as_ref() == self.secret_digest => Ok(()),
+            _ => Err(BrokerError::Unauthorized),
         }
     }
 
-    /// Request a new connection
-    pub async fn request_connection(&self, request: ConnectionRequest) -> anyhow::Result<Uuid> {
-        let request_id = Uuid::new_v4();
-        debug!("New connection request: {:?}", request);
+    /// Sign a routing token for `claims`
+    pub fn mint(&self, claims: &RouteClaims) -> Result<String, BrokerError> {
+        encode(&Header::default(), claims, &self.encoding_key).map_err(|_| BrokerError::InvalidToken)
+    }
 
-        let mut pending = self.pending_requests.write().await;
-        pending.insert(request_id, request);
+    /// Check a routing token presented to node `node_id`
+    pub fn verify(&self, token: &str, node_id: Uuid) -> Result<RouteClaims, BrokerError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = decode::<RouteClaims>(token, &self.decoding_key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => BrokerError::TokenExpired,
+                _ => BrokerError::InvalidToken,
+            })?
+            .claims;
 
-        Ok(request_id)
+        if claims.purpose != ROUTE_TOKEN_PURPOSE {
+            return Err(BrokerError::InvalidToken);
+        }
+        if claims.node_id != node_id {
+            return Err(BrokerError::WrongNode);
+        }
+        Ok(claims)
     }
+}
 
-    /// Get connection status
-    pub async fn get_connection_status(&self, session_id: Uuid) -> Option<EstablishedConnectionType> {
-        let connections = self.active_connections.read().await;
-        connections.get(&session_id).map(|c| c.connection_type.clone())
+/// Claims of a routing token for `session_id` on `node_id`, issued at `now`
+pub fn route_claims(
+    session_id: &str,
+    node_id: Uuid,
+    agent_id: &str,
+    technician_id: &str,
+    now: DateTime<Utc>,
+    ttl_secs: u64,
+) -> RouteClaims {
+    RouteClaims {
+        purpose: ROUTE_TOKEN_PURPOSE.to_string(),
+        session_id: session_id.to_string(),
+        node_id,
+        agent_id: agent_id.to_string(),
+        technician_id: technician_id.to_string(),
+        iat: now.timestamp(),
+        exp: now.timestamp() + ttl_secs as i64,
     }
+}
 
-    /// Close a connection
-    pub async fn close_connection(&self, session_id: Uuid) {
-        let mut connections = self.active_connections.write().await;
-        connections.remove(&session_id);
-        info!("Closed connection for session: {}", session_id);
+impl NodeRegistration {
+    pub fn validate(&self) -> Result<(), BrokerError> {
+        if self.region.trim().is_empty() {
+            return Err(BrokerError::InvalidNode("region must not be empty".to_string()));
+        }
+        if self.capacity == 0 || self.capacity > MAX_NODE_CAPACITY {
+            return Err(BrokerError::InvalidNode(format!("capacity must be between 1 and {}", MAX_NODE_CAPACITY)));
+        }
+        Ok(())
     }
 }
 
-impl Default for ConnectionBroker {
-    fn default() -> Self {
-        Self::new()
+/// Secret a node sent as `Authorization: Bearer <secret>`
+fn presented_secret(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Register a relay node, or update one registering again after a restart
+pub async fn api_register_relay_node(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Json(registration): Json<NodeRegistration>,
+) -> Response {
+    let relay_manager = &app_state.device_manager.relay_manager;
+    if let Err(e) = relay_manager.authenticate_node(presented_secret(&headers)) {
+        return e.into_response();
+    }
+    if let Err(e) = registration.validate() {
+        return e.into_response();
+    }
+
+    let node = app_state.device_manager.register_relay_node(registration, Utc::now()).await;
+    info!("Relay node {} registered at {} ({}, capacity {})", node.id, node.address, node.region, node.capacity);
+    (StatusCode::CREATED, Json(node)).into_response()
+}
+
+/// Record a relay node's load and health
+pub async fn api_relay_node_heartbeat(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Json(heartbeat): Json<NodeHeartbeat>,
+) -> Response {
+    let relay_manager = &app_state.device_manager.relay_manager;
+    if let Err(e) = relay_manager.authenticate_node(presented_secret(&headers)) {
+        return e.into_response();
+    }
+
+    match app_state.device_manager.relay_node_heartbeat(heartbeat, Utc::now()).await {
+        Ok(node) => Json(node).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Every registered relay node with its load and status
+pub async fn api_list_relay_nodes(State(app_state): State<AppState>) -> Response {
+    let relay_manager = &app_state.device_manager.relay_manager;
+    Json(serde_json::json!({
+        "strategy": relay_manager.strategy(),
+        "nodes": relay_manager.list_relay_nodes().await,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::{load_balancer::RouteHints, RelayManager};
+    use chrono::Duration;
+
+    fn policy() -> BrokerPolicy {
+        BrokerPolicy { node_secret: Some("node-secret".to_string()), ..BrokerPolicy::default() }
+    }
+
+    fn registration(id: u128, region: &str) -> NodeRegistration {
+        NodeRegistration {
+            id: Uuid::from_u128(id),
+            address: format!("10.0.0.{}:21117", id).parse().unwrap(),
+            region: region.to_string(),
+            capacity: 100,
+            features: Vec::new(),
+        }
+    }
+
+    fn heartbeat(id: u128, current_load: u32) -> NodeHeartbeat {
+        NodeHeartbeat { id: Uuid::from_u128(id), current_load, health_score: 1.0, capacity: None }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_go_to_the_least_loaded_node() {
+        let manager = RelayManager::with_policy(policy());
+        let now = Utc::now();
+        manager.register_relay_node(registration(1, "us-east"), now).await;
+        manager.register_relay_node(registration(2, "us-west"), now).await;
+        manager.heartbeat(heartbeat(1, 70), now).await.unwrap();
+        manager.heartbeat(heartbeat(2, 20), now).await.unwrap();
+
+        let route = manager.create_route("s1".into(), "agent".into(), "tech".into(), &RouteHints::default(), now).await;
+        assert_eq!(route.relay_node, Some(Uuid::from_u128(2)));
+        assert_eq!(route.relay_address, Some("10.0.0.2:21117".parse().unwrap()));
+
+        // The node accepts the token; other nodes do not
+        let token = route.routing_token.unwrap();
+        let claims = manager.verify_route_token(&token, Uuid::from_u128(2)).unwrap();
+        assert_eq!((claims.session_id.as_str(), claims.agent_id.as_str()), ("s1", "agent"));
+        assert_eq!(manager.verify_route_token(&token, Uuid::from_u128(1)), Err(BrokerError::WrongNode));
+        // The new session counts against the node until its next heartbeat
+        assert_eq!(manager.list_relay_nodes().await[1].current_load, 21);
+    }
+
+    #[tokio::test]
+    async fn test_silent_nodes_are_drained_then_evicted() {
+        let manager = RelayManager::with_policy(policy());
+        let start = Utc::now();
+        manager.register_relay_node(registration(1, "us-east"), start).await;
+        manager.register_relay_node(registration(2, "us-east"), start).await;
+
+        // Node 1 keeps heartbeating, node 2 goes quiet
+        let later = start + Duration::seconds(45);
+        manager.heartbeat(heartbeat(1, 90), later).await.unwrap();
+        manager.sweep_relay_nodes(later).await;
+        let nodes = manager.list_relay_nodes().await;
+        assert_eq!((nodes[0].status, nodes[1].status), (NodeStatus::Active, NodeStatus::Draining));
+
+        // Busier as it is, only node 1 gets new sessions
+        for session in ["s1", "s2"] {
+            let route = manager.create_route(session.into(), "agent".into(), "tech".into(), &RouteHints::default(), later).await;
+            assert_eq!(route.relay_node, Some(Uuid::from_u128(1)));
+        }
+
+        // A heartbeat brings a draining node back
+        manager.heartbeat(heartbeat(2, 0), later).await.unwrap();
+        manager.sweep_relay_nodes(later).await;
+        assert_eq!(manager.list_relay_nodes().await[1].status, NodeStatus::Active);
+
+        // Silent past the eviction threshold, it is forgotten and must register again
+        let evicted = manager.sweep_relay_nodes(later + Duration::seconds(121)).await;
+        assert_eq!(evicted.len(), 2);
+        assert!(manager.list_relay_nodes().await.is_empty());
+        assert_eq!(manager.heartbeat(heartbeat(2, 0), later).await.unwrap_err(), BrokerError::UnknownNode(Uuid::from_u128(2)));
+
+        // With nothing left, sessions fall back to this server
+        let route = manager.create_route("s3".into(), "agent".into(), "tech".into(), &RouteHints::default(), later).await;
+        assert_eq!((route.relay_node, route.routing_token), (None, None));
+    }
+
+    #[test]
+    fn test_route_token_validation() {
+        let key = NodeKey::new("node-secret");
+        let node = Uuid::from_u128(7);
+        let now = Utc::now();
+
+        let token = key.mint(&route_claims("s1", node, "agent", "tech", now, 300)).unwrap();
+        assert_eq!(key.verify(&token, node).unwrap().technician_id, "tech");
+
+        let expired = key.mint(&route_claims("s1", node, "agent", "tech", now - Duration::minutes(10), 300)).unwrap();
+        assert_eq!(key.verify(&expired, node), Err(BrokerError::TokenExpired));
+
+        // Signed with another secret, or tampered with
+        let forged = NodeKey::new("other-secret").mint(&route_claims("s1", node, "agent", "tech", now, 300)).unwrap();
+        assert_eq!(key.verify(&forged, node), Err(BrokerError::InvalidToken));
+        assert_eq!(key.verify(&format!("{}x", token), node), Err(BrokerError::InvalidToken));
+
+        let mut claims = route_claims("s1", node, "agent", "tech", now, 300);
+        claims.purpose = "agent-enrollment".to_string();
+        assert_eq!(key.verify(&key.mint(&claims).unwrap(), node), Err(BrokerError::InvalidToken));
+
+        assert_eq!(key.authenticate(Some("node-secret")), Ok(()));
+        assert_eq!(key.authenticate(Some("node-secret ")), Err(BrokerError::Unauthorized));
+        assert_eq!(key.authenticate(None), Err(BrokerError::Unauthorized));
+    }
+
+    #[tokio::test]
+    async fn test_nodes_cannot_register_without_a_secret() {
+        let manager = RelayManager::new();
+        assert_eq!(manager.authenticate_node(Some("anything")), Err(BrokerError::Disabled));
+
+        let manager = RelayManager::with_policy(policy());
+        assert_eq!(manager.authenticate_node(Some("wrong")), Err(BrokerError::Unauthorized));
+        assert!(registration(1, " ").validate().is_err());
+        assert!(NodeRegistration { capacity: 0, ..registration(1, "us-east") }.validate().is_err());
     }
 }
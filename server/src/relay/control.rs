@@ -0,0 +1,364 @@
+//! Input control between the sessions of one device
+//!
+//! Several technicians may watch a device at once, but only the session
+//! holding the device's control token may inject input. The first control
+//! session to connect takes the token. Another control session asks for it
+//! with `request_control`; the holder is prompted on its WebSocket and
+//! answers with `grant_control` or `deny_control`, and if it stays silent
+//! past the takeover timeout the [`SessionPolicy`] decides. Releasing the
+//! token, or disconnecting, hands it to a waiting requester.
+//!
+//! The tracker only keeps the state; `DeviceManager` applies the outcomes,
+//! telling the sessions and the agent who holds control now.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// How often unanswered takeover requests are checked for their timeout
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// What happens to a takeover request the holder never answered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TakeoverTimeout {
+    /// The holder keeps control
+    #[default]
+    Deny,
+    /// Control passes to the requester
+    Grant,
+}
+
+impl FromStr for TakeoverTimeout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "deny" => Ok(TakeoverTimeout::Deny),
+            "grant" | "auto-grant" | "auto_grant" => Ok(TakeoverTimeout::Grant),
+            other => Err(format!("Unknown takeover timeout policy: {}", other)),
+        }
+    }
+}
+
+/// Per-device session limits and takeover behavior
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionPolicy {
+    /// Concurrent control sessions per device; unset is unlimited
+    pub max_control_sessions: Option<u32>,
+    /// Concurrent view-only sessions per device; unset is unlimited
+    pub max_view_sessions: Option<u32>,
+    /// Seconds the holder has to answer a takeover request
+    pub takeover_timeout_secs: u64,
+    pub takeover_timeout: TakeoverTimeout,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self {
+            max_control_sessions: Some(1),
+            max_view_sessions: None,
+            takeover_timeout_secs: 30,
+            takeover_timeout: TakeoverTimeout::Deny,
+        }
+    }
+}
+
+impl SessionPolicy {
+    /// Concurrent sessions of `session_type` a device may have, if limited
+    pub fn session_limit(&self, session_type: &str) -> Option<u32> {
+        match session_type {
+            "control" => self.max_control_sessions,
+            "view" => self.max_view_sessions,
+            _ => None,
+        }
+    }
+
+    fn takeover_window(&self) -> Duration {
+        Duration::seconds(self.takeover_timeout_secs as i64)
+    }
+}
+
+/// A session waiting for the holder to hand over control
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TakeoverRequest {
+    pub requester: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Why a takeover request did not get control
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyReason {
+    /// The holder turned it down
+    Refused,
+    /// The holder did not answer in time
+    TimedOut,
+    /// Another session is already waiting for control
+    Busy,
+}
+
+/// What a control command changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlOutcome {
+    /// Control moved from `from` to `to`; either may be nobody
+    Transferred { from: Option<Uuid>, to: Option<Uuid> },
+    /// The holder has to answer `request`
+    Requested { holder: Uuid, request: TakeoverRequest },
+    /// `requester` does not get control
+    Denied { requester: Uuid, holder: Uuid, reason: DenyReason },
+    /// `requester` gave up waiting; the holder's prompt is void
+    Withdrawn { requester: Uuid, holder: Uuid },
+    Unchanged,
+}
+
+/// Who controls one device, and who is waiting to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ControlState {
+    pub holder: Option<Uuid>,
+    pub pending: Option<TakeoverRequest>,
+}
+
+impl ControlState {
+    /// Take control if nobody holds it
+    pub fn claim(&mut self, session: Uuid) -> ControlOutcome {
+        if self.holder.is_some() {
+            return ControlOutcome::Unchanged;
+        }
+        self.holder = Some(session);
+        ControlOutcome::Transferred { from: None, to: Some(session) }
+    }
+
+    /// Ask for control; the holder has until `now + window` to answer
+    pub fn request(&mut self, session: Uuid, now: DateTime<Utc>, window: Duration) -> ControlOutcome {
+        let Some(holder) = self.holder else {
+            return self.claim(session);
+        };
+        if holder == session {
+            return ControlOutcome::Unchanged;
+        }
+        match self.pending {
+            Some(pending) if pending.requester == session => ControlOutcome::Unchanged,
+            Some(_) => ControlOutcome::Denied { requester: session, holder, reason: DenyReason::Busy },
+            None => {
+                let request = TakeoverRequest { requester: session, expires_at: now + window };
+                self.pending = Some(request);
+                ControlOutcome::Requested { holder, request }
+            }
+        }
+    }
+
+    /// The holder's answer to the pending request
+    pub fn answer(&mut self, session: Uuid, grant: bool) -> ControlOutcome {
+        if self.holder != Some(session) {
+            return ControlOutcome::Unchanged;
+        }
+        let Some(pending) = self.pending.take() else {
+            return ControlOutcome::Unchanged;
+        };
+        if grant {
+            self.holder = Some(pending.requester);
+            ControlOutcome::Transferred { from: Some(session), to: Some(pending.requester) }
+        } else {
+            ControlOutcome::Denied { requester: pending.requester, holder: session, reason: DenyReason::Refused }
+        }
+    }
+
+    /// Give up control or a pending request, on command or on disconnect.
+    ///
+    /// A holder leaving hands control to the waiting requester, if any.
+    pub fn release(&mut self, session: Uuid) -> ControlOutcome {
+        if self.holder == Some(session) {
+            let to = self.pending.take().map(|pending| pending.requester);
+            self.holder = to;
+            return ControlOutcome::Transferred { from: Some(session), to };
+        }
+        match (self.pending, self.holder) {
+            (Some(pending), Some(holder)) if pending.requester == session => {
+                self.pending = None;
+                ControlOutcome::Withdrawn { requester: session, holder }
+            }
+            _ => ControlOutcome::Unchanged,
+        }
+    }
+
+    /// Settle a request the holder left unanswered past its deadline
+    pub fn expire(&mut self, now: DateTime<Utc>, policy: TakeoverTimeout) -> ControlOutcome {
+        let (Some(holder), Some(pending)) = (self.holder, self.pending) else {
+            return ControlOutcome::Unchanged;
+        };
+        if pending.expires_at > now {
+            return ControlOutcome::Unchanged;
+        }
+        self.pending = None;
+        match policy {
+            TakeoverTimeout::Grant => {
+                self.holder = Some(pending.requester);
+                ControlOutcome::Transferred { from: Some(holder), to: Some(pending.requester) }
+            }
+            TakeoverTimeout::Deny => {
+                ControlOutcome::Denied { requester: pending.requester, holder, reason: DenyReason::TimedOut }
+            }
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.holder.is_none() && self.pending.is_none()
+    }
+}
+
+/// Control state of every device that has a holder or a pending request
+#[derive(Debug, Default)]
+pub struct ControlTracker {
+    devices: RwLock<HashMap<Uuid, ControlState>>,
+}
+
+impl ControlTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `change` on the state of `agent_id`, dropping it once idle
+    async fn update(&self, agent_id: Uuid, change: impl FnOnce(&mut ControlState) -> ControlOutcome) -> ControlOutcome {
+        let mut devices = self.devices.write().await;
+        let state = devices.entry(agent_id).or_default();
+        let outcome = change(state);
+        if state.is_idle() {
+            devices.remove(&agent_id);
+        }
+        outcome
+    }
+
+    pub async fn state(&self, agent_id: Uuid) -> ControlState {
+        self.devices.read().await.get(&agent_id).copied().unwrap_or_default()
+    }
+
+    pub async fn holder(&self, agent_id: Uuid) -> Option<Uuid> {
+        self.state(agent_id).await.holder
+    }
+
+    pub async fn claim(&self, agent_id: Uuid, session: Uuid) -> ControlOutcome {
+        self.update(agent_id, |state| state.claim(session)).await
+    }
+
+    pub async fn request(&self, agent_id: Uuid, session: Uuid, policy: &SessionPolicy, now: DateTime<Utc>) -> ControlOutcome {
+        let window = policy.takeover_window();
+        self.update(agent_id, |state| state.request(session, now, window)).await
+    }
+
+    pub async fn answer(&self, agent_id: Uuid, session: Uuid, grant: bool) -> ControlOutcome {
+        self.update(agent_id, |state| state.answer(session, grant)).await
+    }
+
+    pub async fn release(&self, agent_id: Uuid, session: Uuid) -> ControlOutcome {
+        self.update(agent_id, |state| state.release(session)).await
+    }
+
+    /// Settle every takeover request past its deadline
+    pub async fn expire(&self, now: DateTime<Utc>, policy: TakeoverTimeout) -> Vec<(Uuid, ControlOutcome)> {
+        let mut devices = self.devices.write().await;
+        let outcomes = devices
+            .iter_mut()
+            .map(|(agent_id, state)| (*agent_id, state.expire(now, policy)))
+            .filter(|(_, outcome)| *outcome != ControlOutcome::Unchanged)
+            .collect();
+        devices.retain(|_, state| !state.is_idle());
+        outcomes
+    }
+
+    /// Forget a device that went away along with its sessions
+    pub async fn remove(&self, agent_id: Uuid) {
+        self.devices.write().await.remove(&agent_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_takeover_granted_or_refused_by_holder() {
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let window = Duration::seconds(30);
+        let mut state = ControlState::default();
+
+        assert_eq!(state.claim(first), ControlOutcome::Transferred { from: None, to: Some(first) });
+        assert_eq!(state.claim(second), ControlOutcome::Unchanged);
+
+        // The holder is asked; a second requester has to wait its turn
+        let request = TakeoverRequest { requester: second, expires_at: now + window };
+        assert_eq!(state.request(second, now, window), ControlOutcome::Requested { holder: first, request });
+        assert_eq!(
+            state.request(third, now, window),
+            ControlOutcome::Denied { requester: third, holder: first, reason: DenyReason::Busy }
+        );
+
+        // Only the holder can answer
+        assert_eq!(state.answer(second, true), ControlOutcome::Unchanged);
+        assert_eq!(
+            state.answer(first, false),
+            ControlOutcome::Denied { requester: second, holder: first, reason: DenyReason::Refused }
+        );
+        assert_eq!(state.holder, Some(first));
+
+        state.request(second, now, window);
+        assert_eq!(state.answer(first, true), ControlOutcome::Transferred { from: Some(first), to: Some(second) });
+        assert_eq!(state, ControlState { holder: Some(second), pending: None });
+    }
+
+    #[test]
+    fn test_unanswered_takeover_follows_policy() {
+        let (holder, requester) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let window = Duration::seconds(30);
+
+        for (policy, outcome) in [
+            (TakeoverTimeout::Deny, ControlOutcome::Denied { requester, holder, reason: DenyReason::TimedOut }),
+            (TakeoverTimeout::Grant, ControlOutcome::Transferred { from: Some(holder), to: Some(requester) }),
+        ] {
+            let mut state = ControlState::default();
+            state.claim(holder);
+            state.request(requester, now, window);
+
+            assert_eq!(state.expire(now + Duration::seconds(29), policy), ControlOutcome::Unchanged);
+            assert_eq!(state.expire(now + window, policy), outcome);
+            assert_eq!(state.pending, None);
+        }
+    }
+
+    #[test]
+    fn test_holder_leaving_hands_over_control() {
+        let (holder, requester) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let window = Duration::seconds(30);
+        let mut state = ControlState::default();
+        state.claim(holder);
+
+        // A requester that gives up voids the holder's prompt
+        state.request(requester, now, window);
+        assert_eq!(state.release(requester), ControlOutcome::Withdrawn { requester, holder });
+        assert_eq!(state.pending, None);
+
+        // A holder that leaves passes control to whoever is waiting
+        state.request(requester, now, window);
+        assert_eq!(state.release(holder), ControlOutcome::Transferred { from: Some(holder), to: Some(requester) });
+        assert_eq!(state.release(requester), ControlOutcome::Transferred { from: Some(requester), to: None });
+        assert!(state.is_idle());
+    }
+
+    #[test]
+    fn test_policy_parsing_and_limits() {
+        assert_eq!("auto-grant".parse::<TakeoverTimeout>(), Ok(TakeoverTimeout::Grant));
+        assert_eq!("Deny".parse::<TakeoverTimeout>(), Ok(TakeoverTimeout::Deny));
+        assert!("maybe".parse::<TakeoverTimeout>().is_err());
+
+        let policy = SessionPolicy::default();
+        assert_eq!(policy.session_limit("control"), Some(1));
+        assert_eq!(policy.session_limit("view"), None);
+    }
+}
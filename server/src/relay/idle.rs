@@ -0,0 +1,210 @@
+//! Idle timeout of technician sessions
+//!
+//! The tracker keeps the last activity of every active session: input from
+//! the technician, chat in either direction, or a `keepalive` the viewer
+//! sends when the technician asks to keep an idle session open. A session
+//! that stays idle past its threshold is ended on both sides; a minute
+//! before that the viewer gets an `IdleWarning` so it can offer a keep-alive
+//! button. Agents enforce a longer timeout of their own as a safety net.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// How often sessions are checked for the idle timeout
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long before the disconnect the viewer is warned
+pub const WARNING_LEAD_SECS: i64 = 60;
+
+/// Session types [`IdlePolicy`] knows overrides for, as named in the API
+pub const SESSION_TYPES: [&str; 6] = ["console", "backstage", "adhoc", "file_transfer", "control", "view"];
+
+/// How long sessions may stay idle before the relay ends them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdlePolicy {
+    /// Seconds without activity before a session ends; 0 never ends it
+    pub timeout_secs: u64,
+    /// Replaces `timeout_secs` for the session types listed
+    pub by_session_type: HashMap<String, u64>,
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30 * 60,
+            // Backstage work (scripts, file copies) runs long with nobody typing
+            by_session_type: HashMap::from([("backstage".to_string(), 4 * 60 * 60)]),
+        }
+    }
+}
+
+impl IdlePolicy {
+    /// Idle time after which a session of `session_type` ends, if ever
+    pub fn timeout_for(&self, session_type: &str) -> Option<Duration> {
+        let secs = self.by_session_type.get(session_type).copied().unwrap_or(self.timeout_secs);
+        (secs > 0).then(|| Duration::seconds(secs as i64))
+    }
+}
+
+/// What the relay should do about an idle session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Tell the viewer the session ends at `disconnect_at` unless kept alive
+    Warn { session_id: Uuid, disconnect_at: DateTime<Utc> },
+    /// End the session on both sides
+    End { session_id: Uuid },
+}
+
+#[derive(Debug)]
+struct IdleState {
+    session_type: String,
+    last_activity: DateTime<Utc>,
+    warned: bool,
+}
+
+/// Last activity of every active session
+#[derive(Debug, Default)]
+pub struct IdleTracker {
+    sessions: RwLock<HashMap<Uuid, IdleState>>,
+}
+
+impl IdleTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start the idle clock of a new session
+    pub async fn start(&self, session_id: Uuid, session_type: &str, now: DateTime<Utc>) {
+        self.sessions.write().await.insert(session_id, IdleState {
+            session_type: session_type.to_string(),
+            last_activity: now,
+            warned: false,
+        });
+    }
+
+    /// Note activity in a session, restarting its idle clock
+    pub async fn touch(&self, session_id: Uuid, now: DateTime<Utc>) {
+        if let Some(state) = self.sessions.write().await.get_mut(&session_id) {
+            state.last_activity = state.last_activity.max(now);
+            state.warned = false;
+        }
+    }
+
+    pub async fn last_activity(&self, session_id: Uuid) -> Option<DateTime<Utc>> {
+        self.sessions.read().await.get(&session_id).map(|state| state.last_activity)
+    }
+
+    pub async fn remove(&self, session_id: Uuid) {
+        self.sessions.write().await.remove(&session_id);
+    }
+
+    /// Sessions to warn, once per idle stretch, and sessions to end
+    pub async fn sweep(&self, now: DateTime<Utc>, policy: &IdlePolicy) -> Vec<IdleAction> {
+        let mut actions = Vec::new();
+        let mut sessions = self.sessions.write().await;
+        for (session_id, state) in sessions.iter_mut() {
+            let Some(timeout) = policy.timeout_for(&state.session_type) else {
+                continue;
+            };
+            let disconnect_at = state.last_activity + timeout;
+            if now >= disconnect_at {
+                actions.push(IdleAction::End { session_id: *session_id });
+            } else if !state.warned && now >= disconnect_at - Duration::seconds(WARNING_LEAD_SECS) {
+                state.warned = true;
+                actions.push(IdleAction::Warn { session_id: *session_id, disconnect_at });
+            }
+        }
+        // Ended sessions leave through `remove` once they are torn down
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_manager::{DeviceManager, DeviceRegistration, SessionRequest};
+    use crate::models::SessionType;
+    use crate::relay::outbound::{self, OutboundReceiver};
+    use axum::extract::ws::Message;
+
+    /// Types of the text frames queued for a socket, oldest first
+    async fn kinds(rx: &OutboundReceiver) -> Vec<String> {
+        let mut kinds = Vec::new();
+        while let Ok(Some(message)) = tokio::time::timeout(std::time::Duration::from_millis(50), rx.recv()).await {
+            match message {
+                Message::Text(text) => {
+                    let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    kinds.push(frame["type"].as_str().unwrap_or_default().to_string());
+                }
+                Message::Close(_) => kinds.push("Close".to_string()),
+                _ => {}
+            }
+        }
+        kinds
+    }
+
+    #[tokio::test]
+    async fn test_activity_restarts_idle_clock() {
+        let tracker = IdleTracker::new();
+        let policy = IdlePolicy::default();
+        let start = Utc::now();
+        let (console, backstage) = (Uuid::new_v4(), Uuid::new_v4());
+        tracker.start(console, "console", start).await;
+        tracker.start(backstage, "backstage", start).await;
+
+        // Warned once a minute ahead, then ended; backstage sessions last longer
+        let warn_at = start + Duration::minutes(29);
+        let disconnect_at = start + Duration::minutes(30);
+        assert_eq!(tracker.sweep(warn_at, &policy).await, [IdleAction::Warn { session_id: console, disconnect_at }]);
+        assert!(tracker.sweep(warn_at, &policy).await.is_empty());
+        assert_eq!(tracker.sweep(disconnect_at, &policy).await, [IdleAction::End { session_id: console }]);
+
+        // Activity after the warning starts over, warning included
+        tracker.touch(console, warn_at).await;
+        assert!(tracker.sweep(disconnect_at, &policy).await.is_empty());
+        assert_eq!(tracker.last_activity(console).await, Some(warn_at));
+        let later = warn_at + Duration::minutes(29);
+        assert_eq!(tracker.sweep(later, &policy).await.len(), 1);
+
+        let unlimited = IdlePolicy { timeout_secs: 0, by_session_type: HashMap::new() };
+        assert!(tracker.sweep(start + Duration::days(1), &unlimited).await.is_empty());
+        assert_eq!(policy.timeout_for("backstage"), Some(Duration::hours(4)));
+    }
+
+    #[tokio::test]
+    async fn test_idle_session_is_ended_on_both_sides() {
+        let device_manager = DeviceManager::new();
+        let (agent_tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-01".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = device_manager.register_device(registration, agent_tx).await.unwrap();
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        let request = SessionRequest { agent_id, session_type: SessionType::View, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = device_manager.create_session(request, viewer_tx).await.unwrap();
+        let started = device_manager.idle_tracker.last_activity(session_id).await.unwrap();
+
+        device_manager.expire_idle_sessions(started + Duration::minutes(29)).await;
+        assert_eq!(kinds(&viewer_rx).await, ["IdleWarning"]);
+        assert!(device_manager.get_session(session_id).await.is_some_and(|session| session.status != "ended"));
+
+        device_manager.expire_idle_sessions(started + Duration::minutes(30)).await;
+        assert_eq!(kinds(&viewer_rx).await, ["SessionEnd", "Close"]);
+        assert_eq!(kinds(&agent_rx).await, ["SessionEnd"]);
+        assert_eq!(device_manager.get_session(session_id).await.unwrap().status, "ended");
+        assert_eq!(device_manager.idle_tracker.last_activity(session_id).await, None);
+    }
+}
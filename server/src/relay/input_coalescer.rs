@@ -0,0 +1,321 @@
+//! Coalescing of viewer input before it is forwarded
+//!
+//! Pointers at high polling rates produce hundreds of moves a second, most
+//! of which the agent would apply only to move again a millisecond later.
+//! Input is held for a short window: moves in a row collapse into the latest
+//! position, relative moves and wheel notches into their sum, and whatever
+//! is left goes out
+//! as one [`MessageKind::InputBatch`](super::protocol::MessageKind). Buttons,
+//! keys and anything else are never merged or reordered; they go out at
+//! once, behind the motion that came before them, so clicks land where the
+//! pointer was.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::protocol::{decode_input_batch, encode_input_batch, DecodeError, InputEventBody};
+
+/// How long motion is held for more to merge with, unless configured
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(8);
+
+/// Event type of pointer moves
+const MOUSE_MOVE: &str = "mouse_move";
+
+/// Event type of pointer movement in relative input mode
+const MOUSE_MOVE_RELATIVE: &str = "mouse_move_relative";
+
+/// Event type of wheel notches
+const MOUSE_SCROLL: &str = "mouse_scroll";
+
+/// Wheel notches, as the agent parses them
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "MouseScroll")]
+struct Scroll {
+    delta_x: i32,
+    delta_y: i32,
+}
+
+/// Relative pointer movement, as the agent parses it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "MouseMoveRelative")]
+struct Movement {
+    dx: i32,
+    dy: i32,
+}
+
+/// How many events went into a coalescer and came out of it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputCounters {
+    pub events_in: u64,
+    pub events_out: u64,
+    pub batches: u64,
+}
+
+/// What the caller should do after [`InputCoalescer::push`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flush {
+    /// A button, key or other event arrived; flush now
+    Now,
+    /// Motion started a new batch; flush once the window has passed
+    After(Duration),
+    /// Motion joined a batch that is already waiting
+    Pending,
+}
+
+impl Flush {
+    /// What to do after pushing several events, given what each asked for
+    pub fn then(self, next: Flush) -> Flush {
+        match (self, next) {
+            (Flush::Now, _) | (_, Flush::Now) => Flush::Now,
+            (Flush::After(window), _) | (_, Flush::After(window)) => Flush::After(window),
+            _ => Flush::Pending,
+        }
+    }
+}
+
+/// Motion since the last event that is not motion
+#[derive(Debug, Default)]
+struct Motion {
+    /// Latest pointer move
+    position: Option<Vec<u8>>,
+    movement: Option<(i32, i32)>,
+    scroll: Option<(i32, i32)>,
+}
+
+impl Motion {
+    fn is_empty(&self) -> bool {
+        self.position.is_none() && self.movement.is_none() && self.scroll.is_none()
+    }
+
+    fn drain_into(&mut self, events: &mut Vec<(String, Vec<u8>)>) {
+        if let Some(position) = self.position.take() {
+            events.push((MOUSE_MOVE.to_string(), position));
+        }
+        if let Some((dx, dy)) = self.movement.take() {
+            let data = serde_json::to_vec(&Movement { dx, dy }).expect("movements serialize");
+            events.push((MOUSE_MOVE_RELATIVE.to_string(), data));
+        }
+        if let Some((delta_x, delta_y)) = self.scroll.take() {
+            let data = serde_json::to_vec(&Scroll { delta_x, delta_y }).expect("scrolls serialize");
+            events.push((MOUSE_SCROLL.to_string(), data));
+        }
+    }
+}
+
+/// Input of one session waiting to be forwarded
+#[derive(Debug)]
+pub struct InputCoalescer {
+    window: Duration,
+    /// Events ready to go, oldest first
+    ready: Vec<(String, Vec<u8>)>,
+    motion: Motion,
+    counters: InputCounters,
+}
+
+impl InputCoalescer {
+    /// Hold motion for `window`; a zero window merges only what arrives
+    /// together
+    pub fn new(window: Duration) -> Self {
+        Self { window, ready: Vec::new(), motion: Motion::default(), counters: InputCounters::default() }
+    }
+
+    pub fn counters(&self) -> InputCounters {
+        self.counters
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty() && self.motion.is_empty()
+    }
+
+    /// Queue one event
+    pub fn push(&mut self, event_type: &str, data: &[u8]) -> Flush {
+        let was_empty = self.is_empty();
+        self.counters.events_in += 1;
+        match event_type {
+            MOUSE_MOVE => self.motion.position = Some(data.to_vec()),
+            MOUSE_MOVE_RELATIVE => match serde_json::from_slice::<Movement>(data) {
+                Ok(movement) => {
+                    let (dx, dy) = self.motion.movement.unwrap_or_default();
+                    self.motion.movement = Some((dx.saturating_add(movement.dx), dy.saturating_add(movement.dy)));
+                }
+                Err(_) => return self.push_boundary(event_type, data),
+            },
+            MOUSE_SCROLL => match serde_json::from_slice::<Scroll>(data) {
+                Ok(scroll) => {
+                    let (x, y) = self.motion.scroll.unwrap_or_default();
+                    self.motion.scroll = Some((x.saturating_add(scroll.delta_x), y.saturating_add(scroll.delta_y)));
+                }
+                // Not one we know how to add up; pass it on as it is
+                Err(_) => return self.push_boundary(event_type, data),
+            },
+            _ => return self.push_boundary(event_type, data),
+        }
+        if self.window.is_zero() {
+            Flush::Now
+        } else if was_empty {
+            Flush::After(self.window)
+        } else {
+            Flush::Pending
+        }
+    }
+
+    fn push_boundary(&mut self, event_type: &str, data: &[u8]) -> Flush {
+        self.motion.drain_into(&mut self.ready);
+        self.ready.push((event_type.to_string(), data.to_vec()));
+        Flush::Now
+    }
+
+    /// Queue every event of an encoded batch; [`Flush::Now`] if any asks for
+    /// it
+    pub fn push_batch(&mut self, payload: &[u8]) -> Result<Flush, DecodeError> {
+        let mut flush = Flush::Pending;
+        for event in decode_input_batch(payload)? {
+            flush = flush.then(self.push(event.event_type, event.data));
+        }
+        Ok(flush)
+    }
+
+    /// Everything queued, coalesced, as an encoded batch
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        self.motion.drain_into(&mut self.ready);
+        if self.ready.is_empty() {
+            return None;
+        }
+        let events = std::mem::take(&mut self.ready);
+        self.counters.events_out += events.len() as u64;
+        self.counters.batches += 1;
+        let bodies: Vec<InputEventBody<'_>> = events
+            .iter()
+            .map(|(event_type, data)| InputEventBody { event_type, data })
+            .collect();
+        Some(encode_input_batch(&bodies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mouse_move(x: i32, y: i32) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "type": "MouseMove", "x": x, "y": y })).unwrap()
+    }
+
+    fn button(button: &str, pressed: bool) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "type": "MouseButton", "button": button, "pressed": pressed })).unwrap()
+    }
+
+    fn scroll(delta_y: i32) -> Vec<u8> {
+        serde_json::to_vec(&Scroll { delta_x: 0, delta_y }).unwrap()
+    }
+
+    /// Events of a flushed batch, as (type, JSON)
+    fn events(batch: &[u8]) -> Vec<(String, serde_json::Value)> {
+        decode_input_batch(batch)
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.event_type.to_string(), serde_json::from_slice(event.data).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_motion_collapses_to_latest_position_and_summed_scroll() {
+        let mut coalescer = InputCoalescer::new(DEFAULT_WINDOW);
+        assert_eq!(coalescer.push(MOUSE_MOVE, &mouse_move(1, 1)), Flush::After(DEFAULT_WINDOW));
+        for x in 2..=100 {
+            assert_eq!(coalescer.push(MOUSE_MOVE, &mouse_move(x, x)), Flush::Pending);
+        }
+        coalescer.push(MOUSE_SCROLL, &scroll(-1));
+        coalescer.push(MOUSE_SCROLL, &scroll(-2));
+
+        let batch = events(&coalescer.flush().unwrap());
+        assert_eq!(batch, [
+            (MOUSE_MOVE.to_string(), serde_json::json!({ "type": "MouseMove", "x": 100, "y": 100 })),
+            (MOUSE_SCROLL.to_string(), serde_json::json!({ "type": "MouseScroll", "delta_x": 0, "delta_y": -3 })),
+        ]);
+        assert_eq!(coalescer.flush(), None);
+        assert_eq!(coalescer.counters(), InputCounters { events_in: 102, events_out: 2, batches: 1 });
+    }
+
+    #[test]
+    fn test_relative_movement_is_summed() {
+        let mut coalescer = InputCoalescer::new(DEFAULT_WINDOW);
+        let movement = |dx: i32, dy: i32| serde_json::to_vec(&Movement { dx, dy }).unwrap();
+        assert_eq!(coalescer.push(MOUSE_MOVE_RELATIVE, &movement(3, -1)), Flush::After(DEFAULT_WINDOW));
+        coalescer.push(MOUSE_MOVE_RELATIVE, &movement(4, -2));
+        coalescer.push(MOUSE_MOVE_RELATIVE, &movement(-10, 0));
+        assert_eq!(coalescer.push("mouse_button", &button("Left", true)), Flush::Now);
+        coalescer.push(MOUSE_MOVE_RELATIVE, &movement(i32::MAX, 1));
+        coalescer.push(MOUSE_MOVE_RELATIVE, &movement(1, 1));
+
+        let batch = events(&coalescer.flush().unwrap());
+        assert_eq!(batch, [
+            (MOUSE_MOVE_RELATIVE.to_string(), serde_json::json!({ "type": "MouseMoveRelative", "dx": -3, "dy": -3 })),
+            ("mouse_button".to_string(), serde_json::json!({ "type": "MouseButton", "button": "Left", "pressed": true })),
+            (MOUSE_MOVE_RELATIVE.to_string(), serde_json::json!({ "type": "MouseMoveRelative", "dx": i32::MAX, "dy": 2 })),
+        ]);
+    }
+
+    #[test]
+    fn test_click_drag_release_survives_in_order() {
+        let mut coalescer = InputCoalescer::new(DEFAULT_WINDOW);
+        coalescer.push(MOUSE_MOVE, &mouse_move(10, 10));
+        coalescer.push(MOUSE_MOVE, &mouse_move(20, 20));
+        assert_eq!(coalescer.push("mouse_button", &button("Left", true)), Flush::Now);
+        for x in 21..=60 {
+            coalescer.push(MOUSE_MOVE, &mouse_move(x, 20));
+        }
+        assert_eq!(coalescer.push("mouse_button", &button("Left", false)), Flush::Now);
+
+        let batch = events(&coalescer.flush().unwrap());
+        let summary: Vec<_> = batch
+            .iter()
+            .map(|(event_type, data)| match event_type.as_str() {
+                MOUSE_MOVE => format!("move {}", data["x"]),
+                _ => format!("{} {}", data["button"].as_str().unwrap(), data["pressed"]),
+            })
+            .collect();
+        // The press lands where the pointer was, the drag ends where it went
+        assert_eq!(summary, ["move 20", "Left true", "move 60", "Left false"]);
+    }
+
+    #[test]
+    fn test_keys_are_never_merged_or_reordered() {
+        let mut coalescer = InputCoalescer::new(DEFAULT_WINDOW);
+        let key = |key: &str, pressed: bool| {
+            serde_json::to_vec(&serde_json::json!({ "type": "KeyEvent", "key": key, "pressed": pressed })).unwrap()
+        };
+        coalescer.push("key", &key("A", true));
+        coalescer.push(MOUSE_SCROLL, &scroll(1));
+        coalescer.push("key", &key("A", false));
+        coalescer.push("key", &key("A", true));
+        coalescer.push("key", &key("A", false));
+        // Scrolls the agent would not parse are passed on rather than summed
+        coalescer.push(MOUSE_SCROLL, br#"{"type":"MouseScroll","amount":2}"#);
+        coalescer.push(MOUSE_SCROLL, &scroll(1));
+
+        let batch = events(&coalescer.flush().unwrap());
+        let types: Vec<_> = batch.iter().map(|(event_type, _)| event_type.as_str()).collect();
+        assert_eq!(types, ["key", "mouse_scroll", "key", "key", "key", "mouse_scroll", "mouse_scroll"]);
+        assert_eq!(batch[5].1["amount"], 2);
+    }
+
+    #[test]
+    fn test_batches_and_zero_window() {
+        let mut coalescer = InputCoalescer::new(Duration::ZERO);
+        assert_eq!(coalescer.push(MOUSE_MOVE, &mouse_move(1, 1)), Flush::Now);
+
+        let mut coalescer = InputCoalescer::new(DEFAULT_WINDOW);
+        let moves = [mouse_move(1, 1), mouse_move(2, 2)];
+        let payload = encode_input_batch(&[
+            InputEventBody { event_type: MOUSE_MOVE, data: &moves[0] },
+            InputEventBody { event_type: MOUSE_MOVE, data: &moves[1] },
+        ]);
+        assert_eq!(coalescer.push_batch(&payload).unwrap(), Flush::After(DEFAULT_WINDOW));
+        assert_eq!(coalescer.push_batch(&payload).unwrap(), Flush::Pending);
+        let press = button("Left", true);
+        let payload = encode_input_batch(&[InputEventBody { event_type: "mouse_button", data: &press }]);
+        assert_eq!(coalescer.push_batch(&payload).unwrap(), Flush::Now);
+        assert!(coalescer.push_batch(&[1]).is_err());
+        assert_eq!(events(&coalescer.flush().unwrap()).len(), 2);
+    }
+}
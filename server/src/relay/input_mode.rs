@@ -0,0 +1,59 @@
+//! Absolute and relative pointer input
+//!
+//! Viewers normally send where the pointer is on the remote screen. CAD
+//! tools, games and other apps that capture the cursor want how far it moved
+//! instead, so a viewer can switch its session to relative input: it locks
+//! the pointer locally and sends movement deltas.
+//!
+//! Switching is a handshake. The viewer asks with a `set_input_mode` command,
+//! the relay passes it on as `SetInputMode`, and the agent answers with
+//! `InputModeChanged` naming the mode it actually applies (it stays absolute
+//! where it cannot inject relative motion). Only that answer is recorded in
+//! the session's settings and relayed to the viewer, which streams deltas
+//! once it arrives. Agents may also send `InputModeHint` when the focused app
+//! grabs or releases the cursor; that goes to the viewer as a suggestion.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Settings key of the session's confirmed input mode; absent means absolute
+pub const INPUT_MODE_SETTING: &str = "input_mode";
+
+/// How the viewer's pointer drives the remote one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMode {
+    /// Positions on the remote screen
+    #[default]
+    Absolute,
+    /// Movement deltas, with the viewer's pointer locked
+    Relative,
+}
+
+impl InputMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InputMode::Absolute => "absolute",
+            InputMode::Relative => "relative",
+        }
+    }
+}
+
+impl fmt::Display for InputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for InputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "absolute" => Ok(InputMode::Absolute),
+            "relative" => Ok(InputMode::Relative),
+            other => Err(format!("Unknown input mode: {}", other)),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Liveness of connected devices from their heartbeats
+//!
+//! A device whose heartbeat is half an interval late has missed it. One
+//! missed heartbeat makes it `degraded`; `offline_after_missed` of them
+//! make it `offline`, and the relay closes its socket, which a NAT timeout
+//! or a dropped TCP connection would otherwise leave open indefinitely.
+//! Policies set both the interval and the multiple per group or device,
+//! since a laptop on hotel Wi-Fi misses more heartbeats than a server.
+//!
+//! Status changes are only announced, to the events socket and webhooks,
+//! once the new status has held for `debounce_secs`, so a device that
+//! drops and reconnects within that window does not flap. An agent that
+//! deregisters while shutting down is announced offline at once.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::policies::PolicyDocument;
+
+/// How often device heartbeats are checked
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// WebSocket close code: the device missed too many heartbeats
+pub const CLOSE_HEARTBEAT_TIMEOUT: u16 = 4408;
+
+/// Where a device stands, as stored in `Agent::status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    Online,
+    /// Connected but missing heartbeats
+    Degraded,
+    Offline,
+}
+
+impl DeviceStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceStatus::Online => "online",
+            DeviceStatus::Degraded => "degraded",
+            DeviceStatus::Offline => "offline",
+        }
+    }
+}
+
+/// When devices count as degraded or offline, and how long a status must
+/// hold before it is announced
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LivenessPolicy {
+    /// Seconds between agent heartbeats, unless a policy sets them
+    pub heartbeat_interval_secs: u64,
+    /// Missed heartbeats after which a device is offline, unless a policy
+    /// sets them
+    pub offline_after_missed: u32,
+    /// Seconds a new status must hold before it is announced; 0 announces
+    /// every change
+    pub debounce_secs: u64,
+}
+
+impl Default for LivenessPolicy {
+    fn default() -> Self {
+        Self {
+            // The agent's default heartbeat interval
+            heartbeat_interval_secs: 30,
+            offline_after_missed: 3,
+            debounce_secs: 15,
+        }
+    }
+}
+
+impl LivenessPolicy {
+    /// These thresholds with those `policy` sets for a device
+    pub fn for_device(&self, policy: &PolicyDocument) -> LivenessPolicy {
+        LivenessPolicy {
+            heartbeat_interval_secs: policy.heartbeat_interval_secs.unwrap_or(self.heartbeat_interval_secs),
+            offline_after_missed: policy.offline_after_missed_heartbeats.unwrap_or(self.offline_after_missed),
+            debounce_secs: self.debounce_secs,
+        }
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::seconds(self.debounce_secs as i64)
+    }
+
+    /// Heartbeats missed by `now` since the last one at `last_heartbeat`
+    pub fn missed(&self, last_heartbeat: DateTime<Utc>, now: DateTime<Utc>) -> u32 {
+        let interval = self.heartbeat_interval_secs.max(1) as i64 * 1000;
+        // The next heartbeat counts as missed once it is half an interval
+        // late, and every interval after that adds one more
+        let overdue = (now - last_heartbeat).num_milliseconds() - interval - interval / 2;
+        if overdue <= 0 {
+            return 0;
+        }
+        ((overdue - 1) / interval + 1).try_into().unwrap_or(u32::MAX)
+    }
+
+    /// Status of a connected device whose last heartbeat came at
+    /// `last_heartbeat`
+    pub fn status_at(&self, last_heartbeat: DateTime<Utc>, now: DateTime<Utc>) -> DeviceStatus {
+        match self.missed(last_heartbeat, now) {
+            0 => DeviceStatus::Online,
+            missed if missed >= self.offline_after_missed.max(1) => DeviceStatus::Offline,
+            _ => DeviceStatus::Degraded,
+        }
+    }
+}
+
+/// An announced change of a device's status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub agent_id: Uuid,
+    pub from: DeviceStatus,
+    pub to: DeviceStatus,
+}
+
+#[derive(Debug)]
+struct Liveness {
+    /// Status last seen, and since when
+    observed: DeviceStatus,
+    observed_since: DateTime<Utc>,
+    /// Status last announced
+    announced: DeviceStatus,
+}
+
+/// Seen and announced status of every device since the server started
+#[derive(Debug, Default)]
+pub struct LivenessTracker {
+    devices: RwLock<HashMap<Uuid, Liveness>>,
+}
+
+impl LivenessTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note a device's status at `now`, returning the change to announce
+    /// if the status it settled on differs from the one last announced
+    pub async fn observe(&self, agent_id: Uuid, status: DeviceStatus, now: DateTime<Utc>, debounce: Duration) -> Option<Transition> {
+        let mut devices = self.devices.write().await;
+        // Devices the server has not seen yet were offline as far as anyone knows
+        let liveness = devices.entry(agent_id).or_insert(Liveness {
+            observed: DeviceStatus::Offline,
+            observed_since: now,
+            announced: DeviceStatus::Offline,
+        });
+        if liveness.observed != status {
+            liveness.observed = status;
+            liveness.observed_since = now;
+        }
+        settle(agent_id, liveness, now, debounce)
+    }
+
+    /// Announce a device's status at once, whatever was pending
+    pub async fn announce(&self, agent_id: Uuid, status: DeviceStatus, now: DateTime<Utc>) -> Option<Transition> {
+        self.observe(agent_id, status, now, Duration::zero()).await
+    }
+
+    /// Changes that have held for `debounce` by `now`
+    pub async fn settle(&self, now: DateTime<Utc>, debounce: Duration) -> Vec<Transition> {
+        let mut devices = self.devices.write().await;
+        devices.iter_mut()
+            .filter_map(|(agent_id, liveness)| settle(*agent_id, liveness, now, debounce))
+            .collect()
+    }
+
+    /// Status last announced for a device
+    pub async fn announced(&self, agent_id: Uuid) -> Option<DeviceStatus> {
+        self.devices.read().await.get(&agent_id).map(|liveness| liveness.announced)
+    }
+
+    /// Stop tracking a device that is gone for good
+    pub async fn forget(&self, agent_id: Uuid) {
+        self.devices.write().await.remove(&agent_id);
+    }
+}
+
+fn settle(agent_id: Uuid, liveness: &mut Liveness, now: DateTime<Utc>, debounce: Duration) -> Option<Transition> {
+    if liveness.observed == liveness.announced || now - liveness.observed_since < debounce {
+        return None;
+    }
+    let from = std::mem::replace(&mut liveness.announced, liveness.observed);
+    Some(Transition { agent_id, from, to: liveness.observed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_manager::{DeviceManager, DeviceRegistration};
+    use crate::live_events::{Delivery, LiveEvent, Subscription};
+    use crate::organizations::Tenant;
+    use crate::policies::{PolicyScope, PolicyDocument};
+    use crate::relay::outbound::{self, OutboundReceiver};
+    use axum::extract::ws::Message;
+
+    fn registration(hostname: &str) -> DeviceRegistration {
+        DeviceRegistration {
+            name: None,
+            hostname: hostname.to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: Some(Uuid::new_v4().to_string()),
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        }
+    }
+
+    fn secs(n: i64) -> Duration {
+        Duration::seconds(n)
+    }
+
+    #[test]
+    fn test_missed_heartbeats_degrade_then_take_devices_offline() {
+        let policy = LivenessPolicy { heartbeat_interval_secs: 30, offline_after_missed: 3, debounce_secs: 15 };
+        let last = Utc::now();
+        let at = |n: i64| policy.status_at(last, last + secs(n));
+        assert_eq!(at(0), DeviceStatus::Online);
+        // Half an interval of slack before a heartbeat counts as missed
+        assert_eq!(at(45), DeviceStatus::Online);
+        assert_eq!(at(46), DeviceStatus::Degraded);
+        assert_eq!(at(75), DeviceStatus::Degraded);
+        assert_eq!(at(105), DeviceStatus::Degraded);
+        assert_eq!(at(106), DeviceStatus::Offline);
+
+        let laptops = PolicyDocument { heartbeat_interval_secs: Some(60), offline_after_missed_heartbeats: Some(10), ..Default::default() };
+        let laptops = policy.for_device(&laptops);
+        assert_eq!(laptops.status_at(last, last + secs(106)), DeviceStatus::Degraded);
+        assert_eq!(laptops.status_at(last, last + secs(10 * 60 + 30)), DeviceStatus::Degraded);
+        assert_eq!(laptops.status_at(last, last + secs(10 * 60 + 31)), DeviceStatus::Offline);
+        assert_eq!(laptops.debounce_secs, 15);
+    }
+
+    #[tokio::test]
+    async fn test_only_settled_changes_are_announced() {
+        use DeviceStatus::*;
+        let tracker = LivenessTracker::new();
+        let agent_id = Uuid::new_v4();
+        let start = Utc::now();
+        let debounce = secs(15);
+        let transition = |from, to| Some(Transition { agent_id, from, to });
+
+        // Coming online is announced once it has held for the window
+        assert_eq!(tracker.observe(agent_id, Online, start, debounce).await, None);
+        assert_eq!(tracker.settle(start + secs(14), debounce).await, []);
+        assert_eq!(tracker.settle(start + secs(15), debounce).await, [transition(Offline, Online).unwrap()]);
+        assert_eq!(tracker.announced(agent_id).await, Some(Online));
+
+        // Flapping between degraded, offline and back within the window
+        // announces nothing
+        let flaps = [(20, Degraded), (23, Online), (25, Offline), (28, Online), (31, Degraded), (33, Online)];
+        for (at, status) in flaps {
+            assert_eq!(tracker.observe(agent_id, status, start + secs(at), debounce).await, None);
+            assert_eq!(tracker.settle(start + secs(at + 1), debounce).await, []);
+        }
+        assert_eq!(tracker.settle(start + secs(60), debounce).await, []);
+        assert_eq!(tracker.announced(agent_id).await, Some(Online));
+
+        // A status that sticks is announced once, from when it was first seen
+        assert_eq!(tracker.observe(agent_id, Degraded, start + secs(70), debounce).await, None);
+        assert_eq!(tracker.observe(agent_id, Degraded, start + secs(80), debounce).await, None);
+        assert_eq!(tracker.observe(agent_id, Degraded, start + secs(85), debounce).await, transition(Online, Degraded));
+        assert_eq!(tracker.settle(start + secs(200), debounce).await, []);
+
+        // Degraded devices can still go offline after a blip back online
+        tracker.observe(agent_id, Online, start + secs(90), debounce).await;
+        tracker.observe(agent_id, Offline, start + secs(95), debounce).await;
+        assert_eq!(tracker.settle(start + secs(109), debounce).await, []);
+        assert_eq!(tracker.settle(start + secs(110), debounce).await, [transition(Degraded, Offline).unwrap()]);
+
+        // Announcing skips the window
+        assert_eq!(tracker.observe(agent_id, Online, start + secs(120), debounce).await, None);
+        assert_eq!(tracker.announce(agent_id, Offline, start + secs(121)).await, None);
+        assert_eq!(tracker.settle(start + secs(200), debounce).await, []);
+        tracker.observe(agent_id, Online, start + secs(300), debounce).await;
+        assert_eq!(tracker.announce(agent_id, Offline, start + secs(301)).await, None);
+        assert_eq!(tracker.announce(agent_id, Degraded, start + secs(302)).await, transition(Offline, Degraded));
+    }
+
+    /// Statuses and types of the events delivered so far
+    fn drain(mailbox: &crate::live_events::Mailbox) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Some(Delivery::Event(event)) = mailbox.take() {
+            events.push(match *event {
+                LiveEvent::DeviceOnline { device } => format!("online:{}", device.agent.status),
+                LiveEvent::DeviceDegraded { .. } => "degraded".to_string(),
+                LiveEvent::DeviceOffline { .. } => "offline".to_string(),
+                _ => continue,
+            });
+        }
+        events
+    }
+
+    async fn closed(rx: &OutboundReceiver) -> bool {
+        while let Ok(Some(message)) = tokio::time::timeout(std::time::Duration::from_millis(50), rx.recv()).await {
+            if let Message::Close(Some(frame)) = message {
+                return frame.code == CLOSE_HEARTBEAT_TIMEOUT;
+            }
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_silent_devices_are_degraded_then_disconnected() {
+        let manager = DeviceManager::new()
+            .with_liveness_policy(LivenessPolicy { heartbeat_interval_secs: 30, offline_after_missed: 3, debounce_secs: 10 });
+        let mailbox = manager.live_events.subscribe(Tenant::All, Subscription::default());
+        let (tx, rx) = outbound::channel();
+        let agent_id = manager.register_device(registration("zombie-01"), tx).await.unwrap();
+        let start = manager.get_agent(agent_id).await.unwrap().0.last_seen.unwrap();
+
+        manager.sweep_liveness(start + secs(5)).await;
+        assert!(drain(&mailbox).is_empty());
+        manager.sweep_liveness(start + secs(11)).await;
+        assert_eq!(drain(&mailbox), ["online:online"]);
+
+        // One missed heartbeat, held past the window
+        manager.sweep_liveness(start + secs(50)).await;
+        assert!(drain(&mailbox).is_empty());
+        manager.sweep_liveness(start + secs(60)).await;
+        assert_eq!(drain(&mailbox), ["degraded"]);
+        let (agent, online) = manager.get_agent(agent_id).await.unwrap();
+        assert_eq!((agent.status.as_str(), online), ("degraded", true));
+
+        // Too many: the stale socket is closed and the device goes offline
+        manager.sweep_liveness(start + secs(110)).await;
+        assert!(closed(&rx).await);
+        let (agent, online) = manager.get_agent(agent_id).await.unwrap();
+        assert_eq!((agent.status.as_str(), online), ("offline", false));
+        assert!(drain(&mailbox).is_empty());
+        manager.sweep_liveness(start + secs(120)).await;
+        assert_eq!(drain(&mailbox), ["offline"]);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_within_the_window_do_not_flap() {
+        let manager = DeviceManager::new()
+            .with_liveness_policy(LivenessPolicy { debounce_secs: 3600, ..Default::default() });
+        let mailbox = manager.live_events.subscribe(Tenant::All, Subscription::default());
+        let agent_id = manager.register_device(registration("flappy-01"), outbound::channel().0).await.unwrap();
+        manager.liveness.announce(agent_id, DeviceStatus::Online, Utc::now()).await;
+
+        for _ in 0..3 {
+            manager.disconnect_device(agent_id).await;
+            let registration = DeviceRegistration { agent_id: Some(agent_id.to_string()), ..registration("flappy-01") };
+            manager.register_device(registration, outbound::channel().0).await.unwrap();
+        }
+        manager.sweep_liveness(Utc::now()).await;
+        assert!(drain(&mailbox).is_empty());
+        assert_eq!(manager.liveness.announced(agent_id).await, Some(DeviceStatus::Online));
+
+        // Shutting down is announced at once
+        manager.deregister_device(agent_id, "shutdown").await.unwrap();
+        assert_eq!(drain(&mailbox), ["offline"]);
+    }
+
+    #[tokio::test]
+    async fn test_group_policies_give_devices_longer() {
+        let manager = DeviceManager::new()
+            .with_liveness_policy(LivenessPolicy { heartbeat_interval_secs: 30, offline_after_missed: 3, debounce_secs: 0 });
+        let laptop = manager.register_device(registration("laptop-01"), outbound::channel().0).await.unwrap();
+        let server = manager.register_device(registration("server-01"), outbound::channel().0).await.unwrap();
+        let document = PolicyDocument { heartbeat_interval_secs: Some(60), offline_after_missed_heartbeats: Some(5), ..Default::default() };
+        manager.create_policy(Tenant::All, PolicyScope::Device, Some(laptop), document).await.unwrap();
+
+        let start = manager.get_agent(server).await.unwrap().0.last_seen.unwrap();
+        manager.sweep_liveness(start + secs(120)).await;
+        assert_eq!(manager.get_agent(server).await.map(|(agent, online)| (agent.status, online)), Some(("offline".to_string(), false)));
+        assert_eq!(manager.get_agent(laptop).await.map(|(agent, online)| (agent.status, online)), Some(("degraded".to_string(), true)));
+    }
+}
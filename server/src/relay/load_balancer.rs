@@ -1,466 +1,200 @@
-//! Load balancer for relay node selection and traffic distribution.
-//! This module provides ML-based load balancing with geographic awareness.
-#![allow(dead_code)]
-#![allow(private_interfaces)]
+//! Relay node selection for new sessions
+//!
+//! The balancer only ever sees nodes that accept new sessions; draining and
+//! evicted nodes are filtered out by the broker beforehand. Nodes that are
+//! full or report a poor health score are skipped, and whenever a strategy
+//! has no reason to prefer one node over another it falls back to taking
+//! turns.
 
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    sync::Arc,
-    time::{Duration, Instant},
-};
-use tokio::sync::RwLock;
-use tracing::{debug, info};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use uuid::Uuid;
 
 use super::RelayNode;
 
-/// Geographic location for proximity-based routing
-#[derive(Debug, Clone)]
-pub struct GeoLocation {
-    pub latitude: f64,
-    pub longitude: f64,
-    pub country: String,
-    pub region: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LoadBalancingStrategy {
-    pub algorithm: Algorithm,
-    pub health_threshold: f32,
-    pub max_capacity_ratio: f32,
-    pub geographic_preference: bool,
-    pub latency_weight: f32,
-    pub capacity_weight: f32,
-    pub geographic_weight: f32,
-}
+/// Health score below which a node gets no new sessions
+pub const MIN_HEALTH_SCORE: f32 = 0.5;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Algorithm {
+/// How the broker spreads new sessions across relay nodes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancingStrategy {
+    /// The node with the lowest share of its capacity in use
+    #[default]
+    LeastLoad,
+    /// A node in the agent's or technician's region, least loaded first
+    RegionAffinity,
+    /// Every node in turn
     RoundRobin,
-    LeastConnections,
-    WeightedRoundRobin,
-    GeographicProximity,
-    SmartOptimal,  // Our AI-powered algorithm (10x better)
 }
 
-#[derive(Debug, Clone)]
-struct NodeMetrics {
-    pub connections: u32,
-    pub avg_latency: f32,
-    pub cpu_usage: f32,
-    pub memory_usage: f32,
-    pub bandwidth_usage: f32,
-    pub error_rate: f32,
-    pub last_updated: Instant,
-}
+impl std::str::FromStr for BalancingStrategy {
+    type Err = String;
 
-#[derive(Debug, Clone)]
-struct GeographicZone {
-    pub name: String,
-    pub center_lat: f64,
-    pub center_lon: f64,
-    pub radius_km: f64,
-    pub preferred_nodes: Vec<Uuid>,
-}
-
-/// Next-generation load balancer - 10x better than RustDesk's basic approach
-pub struct LoadBalancer {
-    strategy: LoadBalancingStrategy,
-    node_metrics: Arc<RwLock<HashMap<Uuid, NodeMetrics>>>,
-    geographic_zones: Arc<RwLock<Vec<GeographicZone>>>,
-    routing_history: Arc<RwLock<Vec<RoutingDecision>>>,
-    ml_model: Arc<OptimalRoutingModel>,
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().replace('-', "_").as_str() {
+            "least_load" => Ok(BalancingStrategy::LeastLoad),
+            "region_affinity" => Ok(BalancingStrategy::RegionAffinity),
+            "round_robin" => Ok(BalancingStrategy::RoundRobin),
+            other => Err(format!("unknown balancing strategy '{}'", other)),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-struct RoutingDecision {
-    timestamp: Instant,
-    agent_location: GeoLocation,
-    technician_location: GeoLocation,
-    selected_node: Option<Uuid>,
-    connection_quality: f32,
-    session_duration: Duration,
-    success: bool,
+/// Where the two ends of a session are, as far as the server knows
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteHints {
+    pub agent_region: Option<String>,
+    pub technician_region: Option<String>,
 }
 
-/// AI-powered routing model for optimal relay selection
-struct OptimalRoutingModel {
-    // This would be a machine learning model in production
-    // For now, we'll use heuristics that are still 10x better than RustDesk
-    decision_weights: HashMap<String, f32>,
+/// Picks a relay node for each new session
+#[derive(Debug, Default)]
+pub struct LoadBalancer {
+    strategy: BalancingStrategy,
+    /// Turn counter of the round-robin fallback
+    next: AtomicUsize,
 }
 
 impl LoadBalancer {
-    pub fn new() -> Self {
-        Self {
-            strategy: LoadBalancingStrategy::default(),
-            node_metrics: Arc::new(RwLock::new(HashMap::new())),
-            geographic_zones: Arc::new(RwLock::new(Self::create_default_zones())),
-            routing_history: Arc::new(RwLock::new(Vec::new())),
-            ml_model: Arc::new(OptimalRoutingModel::new()),
-        }
-    }
-    
-    /// Select optimal relay node (10x better than RustDesk's random selection)
-    pub async fn select_optimal_relay(
-        &self,
-        agent_location: &GeoLocation,
-        technician_location: &GeoLocation,
-    ) -> Result<Uuid> {
-        match self.strategy.algorithm {
-            Algorithm::SmartOptimal => {
-                self.smart_optimal_selection(agent_location, technician_location).await
-            }
-            Algorithm::GeographicProximity => {
-                self.geographic_proximity_selection(agent_location, technician_location).await
-            }
-            Algorithm::LeastConnections => {
-                self.least_connections_selection().await
-            }
-            Algorithm::WeightedRoundRobin => {
-                self.weighted_round_robin_selection().await
-            }
-            Algorithm::RoundRobin => {
-                self.round_robin_selection().await
-            }
-        }
+    pub fn new(strategy: BalancingStrategy) -> Self {
+        Self { strategy, next: AtomicUsize::new(0) }
     }
-    
-    /// AI-powered optimal selection (our secret sauce)
-    async fn smart_optimal_selection(
-        &self,
-        agent_location: &GeoLocation,
-        technician_location: &GeoLocation,
-    ) -> Result<Uuid> {
-        info!("Using smart optimal relay selection");
-        
-        // Get all available nodes
-        let available_nodes = self.get_healthy_nodes().await;
-        if available_nodes.is_empty() {
-            return Err(anyhow::anyhow!("No healthy relay nodes available"));
-        }
-        
-        // Calculate scores for each node
-        let mut node_scores = Vec::new();
-        let metrics = self.node_metrics.read().await;
-        
-        for node in available_nodes {
-            let score = self.calculate_node_score(&node, agent_location, technician_location, &metrics).await;
-            node_scores.push((node.id, score));
-        }
-        
-        // Sort by score (highest first)
-        node_scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-        
-        // Select best node
-        let selected_node = node_scores[0].0;
-        
-        // Learn from this decision for future improvements
-        self.record_routing_decision(agent_location, technician_location, Some(selected_node)).await;
-        
-        info!("Selected optimal relay node: {} (score: {:.2})", selected_node, node_scores[0].1);
-        Ok(selected_node)
-    }
-    
-    /// Calculate comprehensive score for a relay node
-    async fn calculate_node_score(
-        &self,
-        node: &RelayNode,
-        agent_location: &GeoLocation,
-        technician_location: &GeoLocation,
-        metrics: &HashMap<Uuid, NodeMetrics>,
-    ) -> f32 {
-        let mut score = 0.0;
-        
-        // 1. Health score (0.0 to 1.0)
-        score += node.health_score * 0.3;
-        
-        // 2. Capacity utilization (lower is better)
-        let capacity_ratio = node.current_load as f32 / node.capacity as f32;
-        score += (1.0 - capacity_ratio) * 0.2;
-        
-        // 3. Geographic proximity (closer is better)
-        let avg_distance = (
-            self.calculate_distance(agent_location, &self.estimate_node_location(node)) +
-            self.calculate_distance(technician_location, &self.estimate_node_location(node))
-        ) / 2.0;
-        let geo_score = 1.0 / (1.0 + avg_distance / 1000.0); // Normalize distance
-        score += (geo_score * 0.2) as f32;
-        
-        // 4. Historical performance
-        if let Some(node_metrics) = metrics.get(&node.id) {
-            // Low latency is good
-            let latency_score = 1.0 / (1.0 + node_metrics.avg_latency / 100.0);
-            score += latency_score * 0.15;
-            
-            // Low error rate is good
-            let reliability_score = 1.0 - node_metrics.error_rate;
-            score += reliability_score * 0.15;
-        }
-        
-        // 5. AI model prediction (this is what makes us 10x better)
-        let ai_score = self.ml_model.predict_performance(node, agent_location, technician_location).await;
-        score += ai_score * 0.1;
-        
-        debug!("Node {} score: {:.3}", node.id, score);
-        score
-    }
-    
-    async fn geographic_proximity_selection(
-        &self,
-        agent_location: &GeoLocation,
-        technician_location: &GeoLocation,
-    ) -> Result<Uuid> {
-        let available_nodes = self.get_healthy_nodes().await;
-        if available_nodes.is_empty() {
-            return Err(anyhow::anyhow!("No healthy relay nodes available"));
-        }
-        
-        // Find node closest to the midpoint
-        let midpoint_lat = (agent_location.latitude + technician_location.latitude) / 2.0;
-        let midpoint_lon = (agent_location.longitude + technician_location.longitude) / 2.0;
-        let midpoint = GeoLocation {
-            latitude: midpoint_lat,
-            longitude: midpoint_lon,
-            country: "".to_string(),
-            region: "".to_string(),
-        };
-        
-        let mut best_node = available_nodes[0].id;
-        let mut best_distance = f64::MAX;
-        
-        for node in available_nodes {
-            let node_location = self.estimate_node_location(&node);
-            let distance = self.calculate_distance(&midpoint, &node_location);
-            
-            if distance < best_distance {
-                best_distance = distance;
-                best_node = node.id;
-            }
-        }
-        
-        Ok(best_node)
+
+    pub fn strategy(&self) -> BalancingStrategy {
+        self.strategy
     }
-    
-    async fn least_connections_selection(&self) -> Result<Uuid> {
-        let available_nodes = self.get_healthy_nodes().await;
-        if available_nodes.is_empty() {
-            return Err(anyhow::anyhow!("No healthy relay nodes available"));
-        }
-        
-        let mut best_node = available_nodes[0].id;
-        let mut least_load = available_nodes[0].current_load;
-        
-        for node in available_nodes {
-            if node.current_load < least_load {
-                least_load = node.current_load;
-                best_node = node.id;
+
+    /// Pick one of `nodes` for a new session, if any has room for it
+    pub fn select(&self, nodes: &[RelayNode], hints: &RouteHints) -> Option<Uuid> {
+        let mut candidates: Vec<&RelayNode> = nodes
+            .iter()
+            .filter(|node| node.current_load < node.capacity && node.health_score >= MIN_HEALTH_SCORE)
+            .collect();
+        // Registration order is not stable across a map, so turns follow node IDs
+        candidates.sort_by_key(|node| node.id);
+
+        match self.strategy {
+            BalancingStrategy::LeastLoad => self.least_loaded(candidates),
+            BalancingStrategy::RegionAffinity => {
+                let affinity = |node: &RelayNode| {
+                    let matches = |region: &Option<String>| region.as_deref().is_some_and(|region| region.eq_ignore_ascii_case(&node.region));
+                    u8::from(matches(&hints.agent_region)) * 2 + u8::from(matches(&hints.technician_region))
+                };
+                // Both ends in the node's region beat the agent's, which beats the technician's
+                let best = candidates.iter().map(|node| affinity(node)).max()?;
+                if best == 0 {
+                    return self.round_robin(candidates);
+                }
+                self.least_loaded(candidates.into_iter().filter(|node| affinity(node) == best).collect())
             }
+            BalancingStrategy::RoundRobin => self.round_robin(candidates),
         }
-        
-        Ok(best_node)
     }
-    
-    async fn weighted_round_robin_selection(&self) -> Result<Uuid> {
-        // TODO: Implement weighted round robin based on node capacity
-        self.round_robin_selection().await
+
+    /// The node using the smallest share of its capacity, taking turns on ties
+    fn least_loaded(&self, candidates: Vec<&RelayNode>) -> Option<Uuid> {
+        let ratio = |node: &RelayNode| node.current_load as f64 / node.capacity as f64;
+        let lowest = candidates.iter().map(|node| ratio(node)).min_by(f64::total_cmp)?;
+        self.round_robin(candidates.into_iter().filter(|node| ratio(node) == lowest).collect())
     }
-    
-    async fn round_robin_selection(&self) -> Result<Uuid> {
-        let available_nodes = self.get_healthy_nodes().await;
-        if available_nodes.is_empty() {
-            return Err(anyhow::anyhow!("No healthy relay nodes available"));
+
+    fn round_robin(&self, candidates: Vec<&RelayNode>) -> Option<Uuid> {
+        if candidates.is_empty() {
+            return None;
         }
-        
-        // Simple round robin - in production, we'd track the last selected index
-        let selected_index = chrono::Utc::now().timestamp() as usize % available_nodes.len();
-        Ok(available_nodes[selected_index].id)
-    }
-    
-    async fn get_healthy_nodes(&self) -> Vec<RelayNode> {
-        // This would come from the relay manager
-        // For now, return empty vec
-        Vec::new()
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(candidates[turn % candidates.len()].id)
     }
-    
-    fn calculate_distance(&self, loc1: &GeoLocation, loc2: &GeoLocation) -> f64 {
-        // Haversine formula for great-circle distance
-        let r = 6371.0; // Earth's radius in km
-        let lat1_rad = loc1.latitude.to_radians();
-        let lat2_rad = loc2.latitude.to_radians();
-        let delta_lat = (loc2.latitude - loc1.latitude).to_radians();
-        let delta_lon = (loc2.longitude - loc1.longitude).to_radians();
-        
-        let a = (delta_lat / 2.0).sin().powi(2) +
-            lat1_rad.cos() * lat2_rad.cos() * (delta_lon / 2.0).sin().powi(2);
-        let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
-        
-        r * c
-    }
-    
-    fn estimate_node_location(&self, node: &RelayNode) -> GeoLocation {
-        // In production, we'd have actual geographic data for each relay node
-        // For now, estimate based on region
-        match node.region.as_str() {
-            "us-east" => GeoLocation {
-                latitude: 39.0458,
-                longitude: -76.6413,
-                country: "US".to_string(),
-                region: "us-east".to_string(),
-            },
-            "us-west" => GeoLocation {
-                latitude: 37.7749,
-                longitude: -122.4194,
-                country: "US".to_string(),
-                region: "us-west".to_string(),
-            },
-            "eu-central" => GeoLocation {
-                latitude: 50.1109,
-                longitude: 8.6821,
-                country: "DE".to_string(),
-                region: "eu-central".to_string(),
-            },
-            _ => GeoLocation {
-                latitude: 0.0,
-                longitude: 0.0,
-                country: "Unknown".to_string(),
-                region: node.region.clone(),
-            },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::connection_broker::NodeStatus;
+    use chrono::Utc;
+
+    fn node(id: u128, region: &str, capacity: u32, current_load: u32) -> RelayNode {
+        RelayNode {
+            id: Uuid::from_u128(id),
+            address: format!("10.0.0.{}:21116", id).parse().unwrap(),
+            region: region.to_string(),
+            capacity,
+            current_load,
+            health_score: 1.0,
+            last_heartbeat: Utc::now(),
+            features: Vec::new(),
+            status: NodeStatus::Active,
         }
     }
-    
-    async fn record_routing_decision(
-        &self,
-        agent_location: &GeoLocation,
-        technician_location: &GeoLocation,
-        selected_node: Option<Uuid>,
-    ) {
-        let decision = RoutingDecision {
-            timestamp: Instant::now(),
-            agent_location: agent_location.clone(),
-            technician_location: technician_location.clone(),
-            selected_node,
-            connection_quality: 1.0, // Will be updated later
-            session_duration: Duration::from_secs(0),
-            success: true, // Will be updated later
-        };
-        
-        let mut history = self.routing_history.write().await;
-        history.push(decision);
-        
-        // Keep only recent decisions (last 10,000)
-        if history.len() > 10000 {
-            history.drain(0..1000);
+
+    fn hints(agent: Option<&str>, technician: Option<&str>) -> RouteHints {
+        RouteHints {
+            agent_region: agent.map(str::to_string),
+            technician_region: technician.map(str::to_string),
         }
     }
-    
-    fn create_default_zones() -> Vec<GeographicZone> {
-        vec![
-            GeographicZone {
-                name: "North America East".to_string(),
-                center_lat: 39.0458,
-                center_lon: -76.6413,
-                radius_km: 2000.0,
-                preferred_nodes: Vec::new(),
-            },
-            GeographicZone {
-                name: "North America West".to_string(),
-                center_lat: 37.7749,
-                center_lon: -122.4194,
-                radius_km: 2000.0,
-                preferred_nodes: Vec::new(),
-            },
-            GeographicZone {
-                name: "Europe Central".to_string(),
-                center_lat: 50.1109,
-                center_lon: 8.6821,
-                radius_km: 1500.0,
-                preferred_nodes: Vec::new(),
-            },
-            GeographicZone {
-                name: "Asia Pacific".to_string(),
-                center_lat: 35.6762,
-                center_lon: 139.6503,
-                radius_km: 3000.0,
-                preferred_nodes: Vec::new(),
-            },
-        ]
-    }
-    
-    /// Update node metrics for better future decisions
-    pub async fn update_node_metrics(&self, node_id: Uuid, metrics: NodeMetrics) {
-        let mut node_metrics = self.node_metrics.write().await;
-        node_metrics.insert(node_id, metrics);
-    }
-    
-    /// Get performance analytics
-    pub async fn get_analytics(&self) -> LoadBalancerAnalytics {
-        let history = self.routing_history.read().await;
-        let total_decisions = history.len();
-        let successful_decisions = history.iter().filter(|d| d.success).count();
-        let avg_session_duration = if !history.is_empty() {
-            history.iter().map(|d| d.session_duration.as_secs()).sum::<u64>() / history.len() as u64
-        } else {
-            0
-        };
-        
-        LoadBalancerAnalytics {
-            total_routing_decisions: total_decisions,
-            success_rate: if total_decisions > 0 {
-                successful_decisions as f32 / total_decisions as f32
-            } else {
-                0.0
-            },
-            avg_session_duration_seconds: avg_session_duration,
-            current_strategy: self.strategy.algorithm.clone(),
+
+    #[test]
+    fn test_least_load_compares_share_of_capacity() {
+        let balancer = LoadBalancer::new(BalancingStrategy::LeastLoad);
+        // 40 of 100 is lighter than 10 of 20, and full or unhealthy nodes are skipped
+        let mut unhealthy = node(4, "us-east", 100, 0);
+        unhealthy.health_score = 0.2;
+        let nodes = [node(1, "us-east", 20, 10), node(2, "us-east", 100, 40), node(3, "us-east", 5, 5), unhealthy];
+        for _ in 0..3 {
+            assert_eq!(balancer.select(&nodes, &RouteHints::default()), Some(Uuid::from_u128(2)));
         }
+
+        // Equally loaded nodes take turns
+        let nodes = [node(1, "us-east", 10, 0), node(2, "us-west", 10, 0)];
+        let first = balancer.select(&nodes, &RouteHints::default()).unwrap();
+        let second = balancer.select(&nodes, &RouteHints::default()).unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(balancer.select(&[node(1, "us-east", 3, 3)], &RouteHints::default()), None);
     }
-}
 
-impl OptimalRoutingModel {
-    fn new() -> Self {
-        let mut decision_weights = HashMap::new();
-        decision_weights.insert("latency".to_string(), 0.3);
-        decision_weights.insert("bandwidth".to_string(), 0.2);
-        decision_weights.insert("reliability".to_string(), 0.2);
-        decision_weights.insert("geographic".to_string(), 0.15);
-        decision_weights.insert("capacity".to_string(), 0.15);
-        
-        Self { decision_weights }
+    #[test]
+    fn test_region_affinity_prefers_the_agents_region() {
+        let balancer = LoadBalancer::new(BalancingStrategy::RegionAffinity);
+        let nodes = [
+            node(1, "us-east", 100, 10),
+            node(2, "eu-central", 100, 60),
+            node(3, "eu-central", 100, 30),
+            node(4, "ap-south", 100, 0),
+        ];
+
+        // Least loaded of the nodes near the agent, even if elsewhere is emptier
+        assert_eq!(balancer.select(&nodes, &hints(Some("eu-central"), Some("us-east"))), Some(Uuid::from_u128(3)));
+        assert_eq!(balancer.select(&nodes, &hints(Some("EU-Central"), None)), Some(Uuid::from_u128(3)));
+        // Without an agent region the technician's counts
+        assert_eq!(balancer.select(&nodes, &hints(None, Some("us-east"))), Some(Uuid::from_u128(1)));
+        // A node in both regions wins outright
+        let shared = [node(1, "us-east", 100, 90), node(2, "eu-central", 100, 0)];
+        assert_eq!(balancer.select(&shared, &hints(Some("us-east"), Some("us-east"))), Some(Uuid::from_u128(1)));
     }
-    
-    async fn predict_performance(
-        &self,
-        _node: &RelayNode,
-        _agent_location: &GeoLocation,
-        _technician_location: &GeoLocation,
-    ) -> f32 {
-        // This would be a real ML model in production
-        // For now, return a baseline score
-        0.8
+
+    #[test]
+    fn test_round_robin_fallback() {
+        let nodes = [node(1, "us-east", 100, 90), node(2, "us-west", 100, 0), node(3, "eu-central", 100, 50)];
+
+        // No node near either end: take turns instead of piling onto one
+        let balancer = LoadBalancer::new(BalancingStrategy::RegionAffinity);
+        let picks: Vec<_> = (0..3).map(|_| balancer.select(&nodes, &hints(Some("ap-south"), None)).unwrap()).collect();
+        assert_eq!(picks, [1, 2, 3].map(Uuid::from_u128));
+
+        let balancer = LoadBalancer::new(BalancingStrategy::RoundRobin);
+        let picks: Vec<_> = (0..4).map(|_| balancer.select(&nodes, &RouteHints::default()).unwrap()).collect();
+        assert_eq!(picks, [1, 2, 3, 1].map(Uuid::from_u128));
+        assert_eq!(balancer.select(&[], &RouteHints::default()), None);
     }
-}
 
-impl Default for LoadBalancingStrategy {
-    fn default() -> Self {
-        Self {
-            algorithm: Algorithm::SmartOptimal,
-            health_threshold: 0.8,
-            max_capacity_ratio: 0.9,
-            geographic_preference: true,
-            latency_weight: 0.3,
-            capacity_weight: 0.3,
-            geographic_weight: 0.4,
-        }
+    #[test]
+    fn test_strategy_names() {
+        assert_eq!("least-load".parse(), Ok(BalancingStrategy::LeastLoad));
+        assert_eq!("REGION_AFFINITY".parse(), Ok(BalancingStrategy::RegionAffinity));
+        assert_eq!("round_robin".parse(), Ok(BalancingStrategy::RoundRobin));
+        assert!("random".parse::<BalancingStrategy>().is_err());
     }
 }
-
-#[derive(Debug, Clone, Serialize)]
-pub struct LoadBalancerAnalytics {
-    pub total_routing_decisions: usize,
-    pub success_rate: f32,
-    pub avg_session_duration_seconds: u64,
-    pub current_strategy: Algorithm,
-}
\ No newline at end of file
@@ -4,20 +4,57 @@
 //! Supports both direct P2P connections and relayed connections through the server.
 
 use anyhow::Result;
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use chrono::{DateTime, Utc};
-use futures_util::{sink::SinkExt, stream::StreamExt};
+use futures_util::stream::{SplitStream, StreamExt};
+use futures_util::SinkExt;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::sync::{mpsc, RwLock};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::device_manager::DeviceManager;
+use crate::auth::enrollment::{EnrollmentService, CLOSE_BAD_REGISTRATION};
+use crate::auth::session_tokens::{SessionScope, SessionTokenClaims};
+use crate::support_codes::{SupportCode, SupportCodeManager, CLOSE_INVALID_SUPPORT_CODE};
+use crate::device_manager::{DeviceManager, DeviceMetrics, DeviceRegistration};
+use crate::deployments;
+use crate::device_records::Inventory;
+use crate::file_browser;
+use crate::file_drop;
+use crate::models::{AuditLog, NetworkInterface};
+use crate::telemetry::Peer;
+use crate::vpn_integration::TailnetAddress;
+use capabilities::AgentCapabilities;
+use chat::ChatParty;
+use input_mode::InputMode;
+use protocol::{Envelope, FileChunkBody, MessageKind, ScreenFrameBody};
+use quality::AgentQualityReport;
 
+pub mod bandwidth;
+pub mod capabilities;
+pub mod chat;
+pub mod codecs;
+// Shared with the agent, which alone compresses frames and counts savings
+#[allow(dead_code)]
+pub mod compression;
+pub mod control;
+pub mod idle;
+pub mod input_coalescer;
+pub mod input_mode;
+pub mod liveness;
 pub mod connection_broker;
 pub mod load_balancer;
+pub mod outbound;
+// Shared with the agent, which alone sends screen frames and flags keyframes
+#[allow(dead_code)]
+pub mod protocol;
+pub mod quality;
 pub mod rendezvous;
+// Shared with the agent, which alone cuts frames into shards and adds parity
+#[allow(dead_code)]
+pub mod datagram;
+pub mod udp_lane;
 
 // ============================================================================
 // Relay Message Types
@@ -87,6 +124,8 @@ pub struct RelayNode {
     pub health_score: f32,
     pub last_heartbeat: DateTime<Utc>,
     pub features: Vec<String>,
+    #[serde(default)]
+    pub status: connection_broker::NodeStatus,
 }
 
 /// Session routing information
@@ -96,57 +135,227 @@ pub struct SessionRoute {
     pub agent_id: String,
     pub technician_id: String,
     pub relay_node: Option<Uuid>,
+    /// Where both ends reach the relay node; unset when relaying through this server
+    pub relay_address: Option<SocketAddr>,
+    /// Signed token the relay node checks before relaying the session
+    pub routing_token: Option<String>,
     pub connection_type: ConnectionType,
     pub created_at: DateTime<Utc>,
     pub last_activity: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConnectionType {
     Direct,      // P2P connection
     RelayedTcp,  // Through relay via TCP
     RelayedUdp,  // Through relay via UDP (faster, less reliable)
-    Hybrid,      // Try UDP first, fall back to TCP
+    Hybrid,      // Frames over the UDP lane, everything else and the fallback over TCP
 }
 
 // ============================================================================
 // WebSocket Handlers (used by main.rs)
 // ============================================================================
 
-/// Handle WebSocket connections for devices (agents)
+/// Time an agent has to send its registration after connecting
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First message an agent sends on the relay WebSocket
+#[derive(Debug, Deserialize)]
+struct AgentRegistration {
+    #[serde(rename = "type")]
+    kind: String,
+    agent_id: String,
+    hostname: String,
+    #[serde(default)]
+    os_info: serde_json::Value,
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(default)]
+    signature: Option<String>,
+    /// What the agent found working on its machine
+    #[serde(default)]
+    capabilities: AgentCapabilities,
+    /// Binary envelope versions the agent speaks; absent on JSON-only agents
+    #[serde(default)]
+    binary_protocol: Vec<u8>,
+    /// Compression codecs the agent speaks; absent on agents predating them
+    #[serde(default)]
+    compression: Vec<String>,
+    /// Network adapters; absent on agents predating Wake-on-LAN
+    #[serde(default)]
+    interfaces: Vec<NetworkInterface>,
+    /// Support code a temporary agent joins with instead of enrolling
+    #[serde(default)]
+    support_code: Option<String>,
+    /// UDP endpoint found with STUN, from agents able to hole punch
+    #[serde(default)]
+    p2p_endpoint: Option<SocketAddr>,
+    #[serde(default)]
+    nat_type: rendezvous::NatType,
+    /// Tailnet address, from agents on Tailscale
+    #[serde(default)]
+    tailnet: Option<TailnetAddress>,
+}
+
+/// A checked registration: the agent, what it sent, the support code it
+/// joined with if any, and the organization it belongs to
+type VerifiedRegistration = (Uuid, AgentRegistration, Option<SupportCode>, Option<Uuid>);
+
+/// Wait for the agent's `AgentRegister` message and check its signature, or
+/// the support code it joins with, which is returned.
+///
+/// On failure the close frame to send back is returned.
+async fn read_registration(
+    receiver: &mut SplitStream<WebSocket>,
+    expected_agent_id: Option<&str>,
+    enrollment: &EnrollmentService,
+    support_codes: &SupportCodeManager,
+) -> Result<VerifiedRegistration, CloseFrame<'static>> {
+    let reject = |code: u16, reason: String| CloseFrame { code, reason: reason.into() };
+
+    let text = match tokio::time::timeout(REGISTRATION_TIMEOUT, receiver.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => text,
+        Ok(_) => return Err(reject(CLOSE_BAD_REGISTRATION, "expected AgentRegister".into())),
+        Err(_) => return Err(reject(CLOSE_BAD_REGISTRATION, "registration timed out".into())),
+    };
+
+    let registration: AgentRegistration = match serde_json::from_str(&text) {
+        Ok(registration) => registration,
+        Err(e) => return Err(reject(CLOSE_BAD_REGISTRATION, format!("malformed registration: {}", e))),
+    };
+    if registration.kind != "AgentRegister" {
+        return Err(reject(CLOSE_BAD_REGISTRATION, "expected AgentRegister".into()));
+    }
+    if expected_agent_id.is_some_and(|id| id != registration.agent_id) {
+        return Err(reject(CLOSE_BAD_REGISTRATION, "agent_id does not match the connection".into()));
+    }
+    let agent_uuid = Uuid::parse_str(&registration.agent_id)
+        .map_err(|_| reject(CLOSE_BAD_REGISTRATION, "agent_id must be a UUID".into()))?;
+
+    if let Some(code) = &registration.support_code {
+        let joined = support_codes
+            .join(code, agent_uuid, chrono::Utc::now())
+            .await
+            .map_err(|e| reject(CLOSE_INVALID_SUPPORT_CODE, e.to_string()))?;
+        let organization_id = joined.organization_id;
+        return Ok((agent_uuid, registration, Some(joined), organization_id));
+    }
+
+    let organization_id = enrollment
+        .verify_registration(agent_uuid, registration.timestamp, registration.signature.as_deref())
+        .await
+        .map_err(|e| reject(e.close_code(), e.to_string()))?;
+
+    Ok((agent_uuid, registration, None, organization_id))
+}
+
+/// Handle WebSocket connections for devices (agents).
+///
+/// The agent's first message must be a signed `AgentRegister`, or one
+/// carrying a valid support code; connections from agents that never
+/// enrolled are closed before they reach the device manager.
 pub async fn handle_websocket(
     socket: WebSocket,
-    agent_id: String,
+    agent_id: Option<String>,
     session_type: String,
+    source_ip: Option<IpAddr>,
     device_manager: Arc<DeviceManager>,
+    enrollment: Arc<EnrollmentService>,
 ) {
+    // Split socket into sender and receiver
+    let (mut sender, mut receiver) = socket.split();
+
+    let (agent_uuid, registration, support_code, organization_id) =
+        match read_registration(&mut receiver, agent_id.as_deref(), &enrollment, &device_manager.support_codes).await {
+            Ok(accepted) => accepted,
+            Err(frame) => {
+                warn!("Rejected agent connection: {} ({})", frame.reason, frame.code);
+                let _ = sender.send(Message::Close(Some(frame))).await;
+                return;
+            }
+        };
+    let agent_id = agent_uuid.to_string();
+
     info!(
         "Agent WebSocket connected: {} (type: {})",
         agent_id, session_type
     );
 
-    // Parse agent UUID
-    let agent_uuid = match Uuid::parse_str(&agent_id) {
-        Ok(uuid) => uuid,
-        Err(e) => {
-            error!("Invalid agent ID {}: {}", agent_id, e);
-            return;
-        }
+    // Create bounded queue for sending messages to this socket
+    let (tx, rx) = outbound::channel();
+    let os_field = |key: &str| registration.os_info.get(key).and_then(|v| v.as_str()).map(str::to_string);
+    let device = DeviceRegistration {
+        name: None,
+        hostname: registration.hostname.clone(),
+        platform: os_field("platform").unwrap_or_else(|| "unknown".to_string()),
+        architecture: os_field("architecture").unwrap_or_else(|| "unknown".to_string()),
+        version: os_field("agent_version").unwrap_or_else(|| "unknown".to_string()),
+        public_key: None,
+        agent_id: Some(agent_id.clone()),
+        interfaces: registration.interfaces.clone(),
+        capabilities: registration.capabilities.clone(),
+        organization_id,
+    };
+    // Registered before the device manager sees the connection, so that an
+    // earlier connection of the same agent cleaning up meanwhile knows it
+    // was replaced
+    let endpoints = rendezvous::AgentEndpoints {
+        public_ip: source_ip,
+        relay_node: None,
+        p2p_endpoint: registration.p2p_endpoint,
+        nat_type: registration.nat_type,
+        tailnet: registration.tailnet.clone(),
     };
+    let connection = device_manager.rendezvous.register(agent_uuid, endpoints, Utc::now()).await;
+    if let Err(e) = device_manager.register_device(device, tx).await {
+        error!("Failed to register agent {}: {}", agent_id, e);
+        device_manager.rendezvous.unregister(agent_uuid, connection.connection_id).await;
+        return;
+    }
 
-    // Split socket into sender and receiver
-    let (mut sender, mut receiver) = socket.split();
+    // Switch bulk traffic to binary envelopes if the agent offered them
+    if let Some(version) = protocol::negotiate(&registration.binary_protocol) {
+        device_manager.set_binary_protocol(agent_uuid, version).await;
+        // Compressed JSON travels in envelopes, so it needs them agreed first
+        let codec = compression::negotiate(&registration.compression);
+        let accepted = serde_json::json!({ "type": "ProtocolAccepted", "version": version, "compression": codec });
+        if let Err(e) = device_manager.send_to_device(agent_uuid, Message::Text(accepted.to_string())).await {
+            warn!("Failed to confirm binary protocol for agent {}: {}", agent_id, e);
+        }
+        // Only after the answer, which the agent must read uncompressed
+        device_manager.set_compression(agent_uuid, codec.is_some()).await;
+    }
+
+    if let Some(code) = &support_code {
+        let accepted = serde_json::json!({
+            "type": "SupportCodeAccepted",
+            "code": code.code,
+            "expires_at": code.expires_at,
+        });
+        if let Err(e) = device_manager.send_to_device(agent_uuid, Message::Text(accepted.to_string())).await {
+            warn!("Failed to confirm support code for agent {}: {}", agent_id, e);
+        }
+    }
+
+    // An empty policy still goes out, so the agent drops any it kept from
+    // before its policies were deleted
+    device_manager.push_policy(agent_uuid).await;
 
-    // Create channel for sending messages to this socket
-    let (_tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    // Commands queued while the agent was away
+    deployments::deliver_queued(&device_manager, agent_uuid, Utc::now()).await;
 
-    // Spawn task to forward messages from channel to socket sender
+    // Time the link to the agent for each of its sessions
+    let probe_task = tokio::spawn(run_latency_probes(device_manager.clone(), agent_uuid));
+
+    // Spawn task to forward queued messages to socket sender
+    let label = format!("agent {}", agent_id);
+    let metrics = device_manager.telemetry.clone();
+    let sender = sender.with(move |message: Message| {
+        metrics.message_sent(Peer::Agent, &message);
+        futures_util::future::ready(Ok::<_, axum::Error>(message))
+    });
     let mut send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(msg).await.is_err() {
-                break;
-            }
-        }
+        outbound::run_writer(sender, rx, &label).await;
     });
 
     // Handle incoming messages from agent
@@ -164,6 +373,7 @@ pub async fn handle_websocket(
                 }
                 Err(e) => {
                     error!("WebSocket error from agent {}: {}", agent_id_clone, e);
+                    device_manager_clone.telemetry.websocket_error(Peer::Agent);
                     break;
                 }
             }
@@ -177,15 +387,48 @@ pub async fn handle_websocket(
     }
 
     // Cleanup
+    probe_task.abort();
+    if !device_manager.rendezvous.unregister(agent_uuid, connection.connection_id).await {
+        // A newer connection of the agent took over; the device is not ours to disconnect
+        info!("Agent WebSocket replaced: {}", agent_id);
+        return;
+    }
     device_manager.disconnect_device(agent_uuid).await;
+    if let Some(code) = device_manager.support_codes.agent_left(agent_uuid).await {
+        device_manager.close_support_code(agent_uuid, &code, "disconnected").await;
+    }
     info!("Agent WebSocket disconnected: {}", agent_id);
 }
 
+/// Send a `LatencyProbe` for each of an agent's sessions every
+/// [`quality::PROBE_INTERVAL`]; the echoes are timed in `handle_agent_command`
+async fn run_latency_probes(device_manager: Arc<DeviceManager>, agent_id: Uuid) {
+    let mut interval = tokio::time::interval(quality::PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        for session in device_manager.get_device_sessions(agent_id).await {
+            let sequence = device_manager.quality_monitor.next_probe(session.id).await;
+            let probe = serde_json::json!({
+                "type": "LatencyProbe",
+                "session_id": session.id.to_string(),
+                "sequence": sequence,
+                "timestamp": Utc::now().timestamp_millis(),
+                "echo": false,
+            });
+            if let Err(e) = device_manager.send_to_device(agent_id, Message::Text(probe.to_string())).await {
+                debug!("Failed to probe agent {}: {}", agent_id, e);
+                return;
+            }
+        }
+    }
+}
+
 /// Handle WebSocket connections for sessions (technicians viewing/controlling agents)
 pub async fn handle_session_websocket(
     socket: WebSocket,
     session_id: String,
     session_type: String,
+    mut grant: SessionTokenClaims,
     device_manager: Arc<DeviceManager>,
 ) {
     info!(
@@ -203,35 +446,51 @@ pub async fn handle_session_websocket(
     };
 
     // Split socket into sender and receiver
-    let (mut sender, mut receiver) = socket.split();
+    let (sender, mut receiver) = socket.split();
 
-    // Create channel for sending messages to this socket
-    let (_tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    // Create bounded queue for sending messages to this socket
+    let (tx, rx) = outbound::channel();
+    let user_id = grant.user_id;
+    device_manager.attach_viewer(session_uuid, user_id, tx).await;
 
-    // Spawn task to forward messages from channel to socket sender
+    // Spawn task to forward queued messages to socket sender
+    let label = format!("session {}", session_id);
+    let metrics = device_manager.telemetry.clone();
+    let sender = sender.with(move |message: Message| {
+        metrics.message_sent(Peer::Viewer, &message);
+        futures_util::future::ready(Ok::<_, axum::Error>(message))
+    });
     let mut send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(msg).await.is_err() {
-                break;
-            }
-        }
+        outbound::run_writer(sender, rx, &label).await;
     });
 
     // Handle incoming messages from technician
     let device_manager_clone = device_manager.clone();
     let session_id_clone = session_id.clone();
     let mut recv_task = tokio::spawn(async move {
-        while let Some(result) = receiver.next().await {
+        loop {
+            // The viewer has until its grant runs out to refresh it
+            let result = tokio::select! {
+                result = receiver.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = tokio::time::sleep(grant.remaining(Utc::now())) => {
+                    info!("Session token for {} expired", session_id_clone);
+                    break;
+                }
+            };
             match result {
                 Ok(msg) => {
                     if let Err(e) =
-                        handle_session_message(&device_manager_clone, &session_id_clone, msg).await
+                        handle_session_message(&device_manager_clone, &session_id_clone, &mut grant, msg).await
                     {
                         warn!("Error handling session message: {}", e);
                     }
                 }
                 Err(e) => {
                     error!("WebSocket error from session {}: {}", session_id_clone, e);
+                    device_manager_clone.telemetry.websocket_error(Peer::Viewer);
                     break;
                 }
             }
@@ -245,7 +504,7 @@ pub async fn handle_session_websocket(
     }
 
     // Cleanup
-    let _ = device_manager.end_session(session_uuid).await;
+    device_manager.detach_viewer(session_uuid, user_id).await;
     info!("Session WebSocket disconnected: {}", session_id);
 }
 
@@ -253,16 +512,37 @@ pub async fn handle_session_websocket(
 // Message Handlers
 // ============================================================================
 
+/// Magic prefix of archive chunks uploaded by agents on the binary channel
+const ARCHIVE_CHUNK_MAGIC: &[u8; 4] = b"GLAR";
+
+/// Split an agent archive chunk into (request id, last flag, payload).
+///
+/// Layout: magic (4) | request id (16) | last flag (1) | payload
+fn decode_archive_chunk(data: &[u8]) -> Option<(Uuid, bool, &[u8])> {
+    if data.len() < 21 || &data[..4] != ARCHIVE_CHUNK_MAGIC {
+        return None;
+    }
+    let request_id = Uuid::from_slice(&data[4..20]).ok()?;
+    Some((request_id, data[20] != 0, &data[21..]))
+}
+
 /// Handle messages received from agents
-async fn handle_agent_message(
+pub(crate) async fn handle_agent_message(
     device_manager: &Arc<DeviceManager>,
     agent_id: &str,
     message: Message,
 ) -> Result<()> {
+    device_manager.telemetry.message_received(Peer::Agent, &message);
     match message {
         Message::Binary(data) => {
-            // Binary data is typically screen frames
-            if let Ok(agent_uuid) = Uuid::parse_str(agent_id) {
+            // Archive uploads and envelopes are tagged; anything else is a screen frame
+            if let Some((request_id, last, payload)) = decode_archive_chunk(&data) {
+                device_manager
+                    .push_archive_chunk(request_id, payload.to_vec(), last)
+                    .await;
+            } else if protocol::is_envelope(&data) {
+                handle_agent_envelope(device_manager, agent_id, &data).await?;
+            } else if let Ok(agent_uuid) = Uuid::parse_str(agent_id) {
                 device_manager
                     .broadcast_screen_frame(agent_uuid, data)
                     .await;
@@ -294,15 +574,103 @@ async fn handle_agent_message(
     Ok(())
 }
 
+/// Handle a binary envelope from an agent
+async fn handle_agent_envelope(device_manager: &Arc<DeviceManager>, agent_id: &str, data: &[u8]) -> Result<()> {
+    let envelope = match Envelope::decode(data) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            warn!("Dropping malformed envelope from agent {}: {}", agent_id, e);
+            return Ok(());
+        }
+    };
+    let payload = match compression::payload(&envelope) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("Dropping {:?} envelope from agent {}: {}", envelope.kind, agent_id, e);
+            return Ok(());
+        }
+    };
+
+    match envelope.kind {
+        MessageKind::ScreenFrame => match ScreenFrameBody::decode(&payload) {
+            Ok(frame) => {
+                if let Ok(agent_uuid) = Uuid::parse_str(agent_id) {
+                    device_manager
+                        .broadcast_screen_frame(agent_uuid, frame.data.to_vec())
+                        .await;
+                }
+            }
+            Err(e) => warn!("Dropping malformed screen frame from agent {}: {}", agent_id, e),
+        },
+        MessageKind::FileChunk => match FileChunkBody::decode(&payload) {
+            // Technician sockets still speak JSON
+            Ok(chunk) => {
+                let cmd = serde_json::json!({
+                    "type": "FileTransfer",
+                    "session_id": envelope.session_id.to_string(),
+                    "transfer_id": chunk.transfer_id,
+                    "file_data": chunk.data,
+                    "filename": chunk.filename,
+                    "total_size": chunk.total_size,
+                    "chunk_index": chunk.chunk_index,
+                    "total_chunks": chunk.total_chunks,
+                });
+                forward_to_agent_session(device_manager, agent_id, cmd).await;
+            }
+            Err(e) => warn!("Dropping malformed file chunk from agent {}: {}", agent_id, e),
+        },
+        MessageKind::TerminalData => {
+            let cmd = serde_json::json!({
+                "type": "TerminalOutput",
+                "session_id": envelope.session_id.to_string(),
+                "data": payload.as_ref(),
+            });
+            forward_to_agent_session(device_manager, agent_id, cmd).await;
+        }
+        MessageKind::InputBatch => {
+            warn!("Agent {} sent an input batch, which only flows to agents", agent_id);
+        }
+        MessageKind::ControlText => match serde_json::from_slice::<serde_json::Value>(&payload) {
+            Ok(cmd) => handle_agent_command(device_manager, agent_id, cmd).await?,
+            Err(e) => warn!("Dropping malformed control message from agent {}: {}", agent_id, e),
+        },
+    }
+    Ok(())
+}
+
+/// Re-encode a technician's `FileTransfer` chunk as a binary envelope
+fn file_chunk_envelope(session_id: Uuid, cmd: &serde_json::Value) -> Option<Vec<u8>> {
+    let data: Vec<u8> = serde_json::from_value(cmd.get("file_data")?.clone()).ok()?;
+    let body = FileChunkBody {
+        transfer_id: cmd.get("transfer_id").and_then(|v| v.as_str()).unwrap_or(""),
+        filename: cmd.get("filename").and_then(|v| v.as_str()).unwrap_or(""),
+        total_size: cmd.get("total_size")?.as_u64()?,
+        chunk_index: u32::try_from(cmd.get("chunk_index")?.as_u64()?).ok()?,
+        total_chunks: u32::try_from(cmd.get("total_chunks")?.as_u64()?).ok()?,
+        data: &data,
+    };
+    let last = body.chunk_index.saturating_add(1) >= body.total_chunks;
+    let envelope = Envelope::new(MessageKind::FileChunk, session_id, &body.encode())
+        .with_flags(if last { protocol::flags::LAST } else { 0 })
+        .encode();
+    Some(envelope)
+}
+
 /// Handle messages received from sessions (technicians)
-async fn handle_session_message(
+pub(crate) async fn handle_session_message(
     device_manager: &Arc<DeviceManager>,
     session_id: &str,
+    grant: &mut SessionTokenClaims,
     message: Message,
 ) -> Result<()> {
+    device_manager.telemetry.message_received(Peer::Viewer, &message);
     match message {
         Message::Binary(data) => {
             // Binary data from technician is typically input events
+            if !grant.allows(SessionScope::Control) || device_manager.is_observer(grant.session_id, grant.user_id).await {
+                deny_scope(device_manager, grant, "input", SessionScope::Control).await;
+                return Ok(());
+            }
             if let Ok(session_uuid) = Uuid::parse_str(session_id) {
                 if let Err(e) = device_manager
                     .forward_input_event(session_uuid, data)
@@ -317,6 +685,29 @@ async fn handle_session_message(
             debug!("Session {} sent text: {}", session_id, text);
 
             if let Ok(cmd) = serde_json::from_str::<serde_json::Value>(&text) {
+                let cmd_type = cmd.get("type").and_then(|v| v.as_str()).unwrap_or("");
+                if cmd_type == "refresh_token" {
+                    refresh_session_token(device_manager, grant).await;
+                    return Ok(());
+                }
+                let scope = SessionScope::for_command(cmd_type);
+                // A colleague shadowing the session only watches until it is theirs
+                let observing = scope != SessionScope::View
+                    && device_manager.is_observer(grant.session_id, grant.user_id).await;
+                if !grant.allows(scope) || observing {
+                    deny_scope(device_manager, grant, cmd_type, scope).await;
+                    return Ok(());
+                }
+                if file_browser::is_fs_command(cmd_type) {
+                    // Answered once the agent replies, without holding up the session's other messages
+                    tokio::spawn(file_browser::relay_session_request(
+                        Arc::clone(device_manager),
+                        grant.session_id,
+                        grant.user_id.to_string(),
+                        cmd,
+                    ));
+                    return Ok(());
+                }
                 handle_session_command(device_manager, session_id, cmd).await?;
             }
         }
@@ -333,6 +724,29 @@ async fn handle_session_message(
     Ok(())
 }
 
+/// Extend the viewer's grant so a long session outlives its first token
+async fn refresh_session_token(device_manager: &Arc<DeviceManager>, grant: &mut SessionTokenClaims) {
+    let reply = match device_manager.session_tokens.refresh(grant, Utc::now()).await {
+        Ok(refreshed) => {
+            *grant = refreshed;
+            serde_json::json!({ "type": "SessionTokenRefreshed", "expires_at": grant.expires_at() })
+        }
+        Err(e) => serde_json::json!({ "type": "SessionTokenRefreshFailed", "error": e.to_string() }),
+    };
+    if let Err(e) = device_manager.send_to_viewer(grant.session_id, grant.user_id, Message::Text(reply.to_string())).await {
+        debug!("Failed to answer token refresh for session {}: {}", grant.session_id, e);
+    }
+}
+
+/// Tell the viewer its token does not cover what it just sent
+async fn deny_scope(device_manager: &Arc<DeviceManager>, grant: &SessionTokenClaims, command: &str, scope: SessionScope) {
+    debug!("Session {} sent {} without the {:?} scope", grant.session_id, command, scope);
+    let denied = serde_json::json!({ "type": "ScopeDenied", "command": command, "scope": scope });
+    if let Err(e) = device_manager.send_to_viewer(grant.session_id, grant.user_id, Message::Text(denied.to_string())).await {
+        debug!("Failed to send scope denial to session {}: {}", grant.session_id, e);
+    }
+}
+
 /// Handle agent control commands
 async fn handle_agent_command(
     device_manager: &Arc<DeviceManager>,
@@ -347,6 +761,30 @@ async fn handle_agent_command(
                 let _ = device_manager.update_device_heartbeat(agent_uuid).await;
             }
         }
+        "Heartbeat" => {
+            // Heartbeats carry the agent's current resource usage
+            if let Ok(agent_uuid) = Uuid::parse_str(agent_id) {
+                let data = cmd.get("data").cloned().unwrap_or_default();
+                let metrics: DeviceMetrics = serde_json::from_value(data).unwrap_or_default();
+                let _ = device_manager.record_metrics(agent_uuid, metrics).await;
+            }
+        }
+        "Inventory" => {
+            // Sent on registration and daily after
+            let agent_uuid = Uuid::parse_str(agent_id)?;
+            let report = match cmd.get("inventory").cloned().map(serde_json::from_value::<Inventory>) {
+                Some(Ok(report)) => report,
+                _ => {
+                    warn!("Malformed inventory from agent {}", agent_id);
+                    return Ok(());
+                }
+            };
+            let changes = device_manager.device_records.record_inventory(agent_uuid, report, Utc::now()).await;
+            if !changes.is_empty() {
+                let fields: Vec<_> = changes.iter().map(|change| change.field.as_str()).collect();
+                info!("Inventory of agent {} changed: {}", agent_id, fields.join(", "));
+            }
+        }
         "capabilities" => {
             // Agent is reporting its capabilities
             debug!("Agent {} capabilities: {:?}", agent_id, cmd.get("data"));
@@ -355,12 +793,288 @@ async fn handle_agent_command(
             // Agent is reporting screen configuration
             debug!("Agent {} screen config: {:?}", agent_id, cmd.get("data"));
         }
-        "error" => {
+        "ArchiveError" => {
+            let request_id = cmd.get("request_id").and_then(|v| v.as_str()).unwrap_or("");
+            let message = cmd.get("message").and_then(|v| v.as_str()).unwrap_or("unknown error");
+            if let Ok(request_uuid) = Uuid::parse_str(request_id) {
+                device_manager.fail_archive(request_uuid, message.to_string()).await;
+            }
+        }
+        "RegistryResult" => {
+            let request_id = cmd.get("request_id").and_then(|v| v.as_str()).unwrap_or("");
+            if let Ok(request_uuid) = Uuid::parse_str(request_id) {
+                device_manager.complete_registry_request(request_uuid, cmd).await;
+            }
+        }
+        "ToolResult" => {
+            if let Ok(agent_uuid) = Uuid::parse_str(agent_id) {
+                deployments::record_result(device_manager, agent_uuid, cmd).await;
+            }
+        }
+        "FsResult" => {
+            let request_id = cmd.get("request_id").and_then(|v| v.as_str()).unwrap_or("");
+            if let Ok(request_uuid) = Uuid::parse_str(request_id) {
+                device_manager.complete_fs_request(request_uuid, cmd).await;
+            }
+        }
+        "ElevationResult" => {
+            let session_id = cmd.get("session_id").and_then(|v| v.as_str()).unwrap_or("");
+            if let Ok(session_uuid) = Uuid::parse_str(session_id) {
+                let success = cmd.get("success").and_then(|v| v.as_bool()).unwrap_or(false);
+                device_manager.pam_manager.record_agent_elevation(session_uuid, success, cmd.clone()).await;
+                let action = if success { "agent_elevation_granted" } else { "agent_elevation_failed" };
+                device_manager.record_audit(
+                    AuditLog::new("pam", action)
+                        .actor(agent_id.to_string())
+                        .session(session_uuid)
+                        .details(cmd.clone()),
+                ).await;
+            }
+            // The technician's session window runs commands with the new rights
+            forward_to_agent_session(device_manager, agent_id, cmd).await;
+        }
+        "LatencyProbe" => {
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            let sequence = cmd.get("sequence").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+            if cmd.get("echo").and_then(|v| v.as_bool()).unwrap_or(false) {
+                device_manager.quality_monitor.record_echo(session_uuid, sequence).await;
+            } else {
+                // The agent times its own side of the link
+                let mut echo = cmd.clone();
+                echo["echo"] = serde_json::Value::Bool(true);
+                let agent_uuid = Uuid::parse_str(agent_id)?;
+                if let Err(e) = device_manager.send_to_device(agent_uuid, Message::Text(echo.to_string())).await {
+                    debug!("Failed to echo latency probe to agent {}: {}", agent_id, e);
+                }
+            }
+        }
+        "QualityReport" => {
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            let report: AgentQualityReport = match cmd.get("report").cloned().map(serde_json::from_value) {
+                Some(Ok(report)) => report,
+                _ => {
+                    warn!("Malformed quality report from agent {}", agent_id);
+                    return Ok(());
+                }
+            };
+            let quality = device_manager.quality_monitor.record_report(session_uuid, report).await;
+
+            // The viewer renders this as its connection badge
+            let update = serde_json::json!({ "type": "QualityReport", "quality": quality });
+            if let Err(e) = device_manager.send_to_session(session_uuid, Message::Text(update.to_string())).await {
+                debug!("Failed to push quality to session {}: {}", session_uuid, e);
+            }
+        }
+        "StreamConfig" => {
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            let config = cmd.get("config").cloned().unwrap_or_default();
+            if let Err(e) = device_manager.record_stream_config(session_uuid, config).await {
+                debug!("Failed to pass stream config to session {}: {}", session_uuid, e);
+            }
+        }
+        "UdpLaneClosed" => {
+            // The agent gave up on the lane; its frames are back on this socket
+            let agent_uuid = Uuid::parse_str(agent_id)?;
+            if let Some(lane) = &device_manager.udp_lane {
+                let sessions = lane.close(agent_uuid).await;
+                if !sessions.is_empty() {
+                    let reason = cmd.get("reason").and_then(|v| v.as_str()).unwrap_or("no reason given");
+                    info!("Agent {} fell back from its UDP lane: {}", agent_id, reason);
+                    device_manager.udp_lane_closed(agent_uuid, sessions, false).await;
+                }
+            }
+        }
+        "CursorUpdate" => {
+            // Small and frequent; goes out ahead of queued frames
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            if let Err(e) = device_manager.relay_cursor_update(session_uuid, cmd).await {
+                debug!("Failed to pass cursor update to session {}: {}", session_uuid, e);
+            }
+        }
+        "InputModeChanged" => {
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            let mode = cmd.get("mode").and_then(|v| v.as_str()).unwrap_or("").parse::<InputMode>().unwrap_or_default();
+            let reason = cmd.get("reason").and_then(|v| v.as_str());
+            if let Err(e) = device_manager.confirm_input_mode(session_uuid, mode, reason).await {
+                debug!("Failed to confirm {} input for session {}: {}", mode, session_uuid, e);
+            }
+        }
+        "InputPreviewChanged" => {
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            let enabled = cmd.get("enabled").and_then(|v| v.as_bool()).unwrap_or(false);
+            info!("Input preview {} for session {}", if enabled { "on" } else { "off" }, session_uuid);
+            if let Err(e) = device_manager.confirm_input_preview(session_uuid, enabled).await {
+                debug!("Failed to confirm input preview for session {}: {}", session_uuid, e);
+            }
+        }
+        "InputPreviewed" => {
+            // An event the agent validated without injecting it; the viewer
+            // marks where it would have landed
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            if let Err(e) = device_manager.send_to_session(session_uuid, Message::Text(cmd.to_string())).await {
+                debug!("Failed to acknowledge previewed input to session {}: {}", session_uuid, e);
+            }
+        }
+        "SessionEnd" => {
+            // The agent is ending a session itself, usually because it is
+            // shutting down; ending it here echoes SessionEnd back as the ack.
+            // A reason means its watchdog gave up on capture, encoder or
+            // input, or a permission they need was taken away.
+            let reason = cmd.get("reason").and_then(|v| v.as_str());
+            match owned_session(device_manager, agent_id, &cmd).await {
+                Some(session_uuid) if reason.is_some() => {
+                    let failure = session_failure(&cmd);
+                    warn!("Agent {} ended session {}: {}", agent_id, session_uuid, failure["reason"]);
+                    fail_agent_session(device_manager, agent_id, session_uuid, failure).await;
+                }
+                Some(session_uuid) => {
+                    if let Err(e) = device_manager.end_session(session_uuid).await {
+                        debug!("Agent {} ended session {}: {}", agent_id, session_uuid, e);
+                    }
+                }
+                None => {
+                    // Already gone; acknowledge anyway so the agent doesn't wait
+                    let agent_uuid = Uuid::parse_str(agent_id)?;
+                    let _ = device_manager.send_to_device(agent_uuid, Message::Text(cmd.to_string())).await;
+                }
+            }
+        }
+        "BannerAcknowledged" => {
+            // The end user accepted the session banner; the agent starts the session next
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            let banner_id = cmd.get("banner_id").and_then(|v| v.as_str()).unwrap_or("");
+            let Ok(banner_uuid) = Uuid::parse_str(banner_id) else {
+                warn!("Agent {} acknowledged malformed banner {}", agent_id, banner_id);
+                return Ok(());
+            };
+            if let Err(e) = device_manager.branding_manager.acknowledge_banner(banner_uuid, agent_id.to_string()).await {
+                warn!("Agent {} acknowledged banner {}: {}", agent_id, banner_id, e);
+            }
+            device_manager.record_audit(
+                AuditLog::new("session", "banner_acknowledged")
+                    .actor(agent_id.to_string())
+                    .session(session_uuid)
+                    .details(serde_json::json!({ "banner_id": banner_uuid })),
+            ).await;
+        }
+        "SessionResponse" => {
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            if cmd.get("accepted").and_then(|v| v.as_bool()).unwrap_or(false) {
+                debug!("Agent {} started session {}", agent_id, session_uuid);
+                return Ok(());
+            }
+            // The agent could not start it; the viewer shows the failed check
+            // instead of waiting for frames that never come
+            let failure = session_failure(&cmd);
+            warn!("Agent {} could not start session {}: {}", agent_id, session_uuid, failure["reason"]);
+            fail_agent_session(device_manager, agent_id, session_uuid, failure).await;
+        }
+        "SessionCancelled" => {
+            // The end user declined the banner, or nobody answered it
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            let reason = cmd.get("reason").and_then(|v| v.as_str()).unwrap_or("unknown");
+            info!("Agent {} cancelled session {}: {}", agent_id, session_uuid, reason);
+            device_manager.record_audit(
+                AuditLog::new("session", "session_cancelled")
+                    .actor(agent_id.to_string())
+                    .session(session_uuid)
+                    .details(serde_json::json!({ "reason": reason })),
+            ).await;
+            let _ = device_manager.send_to_session(session_uuid, Message::Text(cmd.to_string())).await;
+            if let Err(e) = device_manager.end_session(session_uuid).await {
+                debug!("Agent {} cancelled session {}: {}", agent_id, session_uuid, e);
+            }
+        }
+        "AccessPaused" => {
+            // The end user paused remote access from the tray, or resumed it
+            let agent_uuid = Uuid::parse_str(agent_id)?;
+            let until = match cmd.get("until").cloned().map(serde_json::from_value::<Option<DateTime<Utc>>>) {
+                Some(Ok(until)) => until,
+                None => None,
+                Some(Err(_)) => {
+                    warn!("Malformed access pause from agent {}", agent_id);
+                    return Ok(());
+                }
+            };
+            device_manager.set_access_paused(agent_uuid, until).await;
+        }
+        "JoinSupportCode" => {
+            // The end user typed a technician's code into the tray
+            let agent_uuid = Uuid::parse_str(agent_id)?;
+            let code = cmd.get("code").and_then(|v| v.as_str()).unwrap_or("");
+            let answer = match device_manager.join_support_code(agent_uuid, code).await {
+                Ok(joined) => {
+                    info!("Agent {} joined support code {}", agent_id, joined.code);
+                    serde_json::json!({ "type": "SupportCodeAccepted", "code": joined.code, "expires_at": joined.expires_at })
+                }
+                Err(e) => {
+                    info!("Agent {} could not join support code {}: {}", agent_id, code, e);
+                    serde_json::json!({ "type": "SupportCodeClosed", "code": code, "reason": e.to_string() })
+                }
+            };
+            let _ = device_manager.send_to_device(agent_uuid, Message::Text(answer.to_string())).await;
+        }
+        "ChatMessage" | "ChatReceipt" => {
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            if cmd_type == "ChatMessage" {
+                chat::relay_message(device_manager, session_uuid, ChatParty::EndUser, &cmd).await;
+            } else {
+                chat::relay_receipt(device_manager, session_uuid, ChatParty::EndUser, &cmd).await;
+            }
+        }
+        "AgentDeregister" => {
+            let reason = cmd.get("reason").and_then(|v| v.as_str()).unwrap_or("unspecified");
+            let agent_uuid = Uuid::parse_str(agent_id)?;
+            if let Err(e) = device_manager.deregister_device(agent_uuid, reason).await {
+                debug!("Ignoring deregister from agent {}: {}", agent_id, e);
+            }
+        }
+        "FileTransferResume" | "FileTransferComplete" | "FileTransferCancel" => {
+            // Answers about files the relay uploads for a drop stay with that upload
+            let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+                return Ok(());
+            };
+            if !device_manager.deliver_drop_reply(session_uuid, &cmd).await {
+                forward_to_agent_session(device_manager, agent_id, cmd).await;
+            }
+        }
+        "InputModeHint" | "ClipboardSync" | "FileTransferStart" | "FileTransfer" | "MonitorControl"
+        | "P2PHandshake" | "P2PResponse" | "TerminalOpened" | "TerminalOutput" | "TerminalClosed"
+        | "TerminalScrollback" | "SessionHealth" => {
+            forward_to_agent_session(device_manager, agent_id, cmd).await;
+        }
+        "Error" => {
             warn!(
                 "Agent {} error: {:?}",
                 agent_id,
                 cmd.get("message").and_then(|v| v.as_str())
             );
+            // Errors about a session, such as rejected input, go to its viewers
+            if cmd.get("session_id").is_some() {
+                forward_to_agent_session(device_manager, agent_id, cmd).await;
+            }
         }
         _ => {
             debug!("Unknown command from agent {}: {}", agent_id, cmd_type);
@@ -369,9 +1083,79 @@ async fn handle_agent_command(
     Ok(())
 }
 
+/// Why an agent's `SessionResponse` or `SessionEnd` gave up on a session,
+/// with the pre-flight report when it ran one and the error code the
+/// viewer offers a fix for
+fn session_failure(response: &serde_json::Value) -> serde_json::Value {
+    let reason = response.get("reason").and_then(|v| v.as_str()).unwrap_or("The agent could not start the session");
+    let mut failure = serde_json::json!({ "reason": reason });
+    for field in ["readiness", "error_code"] {
+        if let Some(value) = response.get(field).filter(|value| !value.is_null()) {
+            failure[field] = value.clone();
+        }
+    }
+    failure
+}
+
+/// End a session the agent gave up on, auditing `failure` and showing it
+/// to the viewer as `SessionFailed`
+async fn fail_agent_session(device_manager: &Arc<DeviceManager>, agent_id: &str, session_uuid: Uuid, failure: serde_json::Value) {
+    device_manager.record_audit(
+        AuditLog::new("session", "session_failed")
+            .actor(agent_id.to_string())
+            .session(session_uuid)
+            .details(failure.clone()),
+    ).await;
+    let mut failed = failure.clone();
+    failed["type"] = "SessionFailed".into();
+    failed["session_id"] = session_uuid.to_string().into();
+    let _ = device_manager.send_to_session(session_uuid, Message::Text(failed.to_string())).await;
+    if let Err(e) = device_manager.fail_session(session_uuid, failure).await {
+        debug!("Agent {} failed session {}: {}", agent_id, session_uuid, e);
+    }
+}
+
+/// Relay an agent message to the technician of one of that agent's sessions
+async fn forward_to_agent_session(device_manager: &Arc<DeviceManager>, agent_id: &str, cmd: serde_json::Value) {
+    let cmd_type = cmd.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    let Some(session_uuid) = owned_session(device_manager, agent_id, &cmd).await else {
+        return;
+    };
+
+    if let Err(e) = device_manager
+        .send_to_session(session_uuid, Message::Text(cmd.to_string()))
+        .await
+    {
+        debug!("Failed to forward {} to session {}: {}", cmd_type, session_uuid, e);
+    }
+}
+
+/// The session an agent message names, if it belongs to that agent
+async fn owned_session(device_manager: &Arc<DeviceManager>, agent_id: &str, cmd: &serde_json::Value) -> Option<Uuid> {
+    let cmd_type = cmd.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    let session_id = cmd.get("session_id").and_then(|v| v.as_str()).unwrap_or("");
+    let (Ok(agent_uuid), Ok(session_uuid)) = (Uuid::parse_str(agent_id), Uuid::parse_str(session_id)) else {
+        return None;
+    };
+
+    let owns_session = device_manager
+        .get_device_sessions(agent_uuid)
+        .await
+        .iter()
+        .any(|session| session.id == session_uuid);
+    if !owns_session {
+        warn!("Agent {} sent {} for foreign session {}", agent_id, cmd_type, session_id);
+        return None;
+    }
+    Some(session_uuid)
+}
+
+/// Encoder profiles an agent accepts in `SetEncoderProfile`
+const ENCODER_PROFILES: &[&str] = &["low-latency", "balanced", "quality", "archival", "low-bandwidth"];
+
 /// Handle session control commands
 async fn handle_session_command(
-    _device_manager: &Arc<DeviceManager>,
+    device_manager: &Arc<DeviceManager>,
     session_id: &str,
     cmd: serde_json::Value,
 ) -> Result<()> {
@@ -382,18 +1166,225 @@ async fn handle_session_command(
             // Technician is requesting screen capture
             debug!("Session {} requesting screen", session_id);
         }
+        "decode_failed" => {
+            // The viewer cannot decode the codec the agent picked; the agent
+            // steps down and confirms with a new StreamConfig
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let reason = cmd.get("reason").and_then(|v| v.as_str());
+            warn!("Session {} cannot decode its stream: {}", session_id, reason.unwrap_or("no reason given"));
+            if let Err(e) = device_manager.downgrade_stream(session_uuid, reason).await {
+                warn!("Failed to downgrade the stream of session {}: {}", session_id, e);
+            }
+        }
+        "set_cursor_streaming" => {
+            // Viewers that do not draw a cursor overlay can turn the updates off
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let enabled = cmd.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true);
+            if let Err(e) = device_manager.set_cursor_streaming(session_uuid, enabled).await {
+                warn!("Failed to set cursor streaming for session {}: {}", session_id, e);
+            }
+        }
+        "set_input_mode" => {
+            // The viewer locked its pointer and wants to send movement
+            // instead of positions, or let go of it; the agent confirms
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let mode = match cmd.get("mode").and_then(|v| v.as_str()).unwrap_or("").parse::<InputMode>() {
+                Ok(mode) => mode,
+                Err(e) => {
+                    warn!("Session {}: {}", session_id, e);
+                    return Ok(());
+                }
+            };
+            if let Err(e) = device_manager.set_input_mode(session_uuid, mode).await {
+                warn!("Failed to switch session {} to {} input: {}", session_id, mode, e);
+            }
+        }
+        "set_input_preview" => {
+            // Training and shadowing: the agent checks the technician's
+            // input but leaves the user's pointer and keyboard alone
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let enabled = cmd.get("enabled").and_then(|v| v.as_bool()).unwrap_or(false);
+            if let Err(e) = device_manager.set_input_preview(session_uuid, enabled).await {
+                warn!("Failed to set input preview for session {}: {}", session_id, e);
+            }
+        }
+        "complete_transfer" => {
+            // Either side of a shadowing transfer says the colleague takes
+            // over now rather than when the technician leaves
+            let session_uuid = Uuid::parse_str(session_id)?;
+            if let Err(e) = device_manager.complete_transfer(session_uuid).await {
+                warn!("Session {}: {}", session_id, e);
+            }
+        }
+        "set_bandwidth" => {
+            // The technician caps the stream for a metered link, or lifts
+            // the cap with null; the policy ceiling applies either way
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let kbps = match cmd.get("kbps").and_then(|v| v.as_u64()) {
+                Some(kbps) => match bandwidth::validate_cap(u32::try_from(kbps).unwrap_or(u32::MAX)) {
+                    Ok(kbps) => Some(kbps),
+                    Err(e) => {
+                        warn!("Session {}: {}", session_id, e);
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+            match device_manager.set_bandwidth_cap(session_uuid, kbps).await {
+                Ok(cap) => debug!("Session {} bandwidth cap: {:?} kbit/s", session_id, cap),
+                Err(e) => warn!("Failed to cap bandwidth of session {}: {}", session_id, e),
+            }
+        }
+        "request_keyframe" => {
+            // The viewer lost its reference frame, e.g. after dropped frames or a decode error
+            let session_uuid = Uuid::parse_str(session_id)?;
+            if let Err(e) = device_manager.request_keyframe(session_uuid).await {
+                warn!("Failed to request a keyframe for session {}: {}", session_id, e);
+            }
+        }
         "set_quality" => {
             // Technician is adjusting quality settings
             let quality = cmd.get("quality").and_then(|v| v.as_u64()).unwrap_or(80);
             debug!("Session {} set quality: {}", session_id, quality);
+            let session_uuid = Uuid::parse_str(session_id)?;
+
+            // A named profile switches the agent's encoder preset mid-session
+            if let Some(profile) = cmd.get("profile").and_then(|v| v.as_str()) {
+                if !ENCODER_PROFILES.contains(&profile) {
+                    warn!("Session {} requested unknown encoder profile: {}", session_id, profile);
+                    return Ok(());
+                }
+                let command = serde_json::json!({
+                    "type": "SetEncoderProfile",
+                    "session_id": session_id,
+                    "profile": profile,
+                });
+                if let Err(e) = device_manager
+                    .send_to_session_agent(session_uuid, Message::Text(command.to_string()))
+                    .await
+                {
+                    warn!("Failed to forward encoder profile for session {}: {}", session_id, e);
+                }
+            }
+
+            // The agent's adaptive streaming ramps up to this ceiling
+            let command = serde_json::json!({
+                "type": "SetQuality",
+                "session_id": session_id,
+                "quality": quality.min(100),
+            });
+            if let Err(e) = device_manager
+                .send_to_session_agent(session_uuid, Message::Text(command.to_string()))
+                .await
+            {
+                warn!("Failed to forward quality for session {}: {}", session_id, e);
+            }
+        }
+        "ClipboardSync" => {
+            // The technician copied something; the agent decides whether to apply it
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let command = serde_json::json!({
+                "type": "ClipboardSync",
+                "session_id": session_id,
+                "content": cmd.get("content").and_then(|v| v.as_str()).unwrap_or(""),
+                "content_type": cmd.get("content_type").and_then(|v| v.as_str()).unwrap_or("text/plain"),
+            });
+            if let Err(e) = device_manager
+                .send_to_session_agent(session_uuid, Message::Text(command.to_string()))
+                .await
+            {
+                warn!("Failed to forward clipboard for session {}: {}", session_id, e);
+            }
+        }
+        "FileTransferStart" | "FileTransfer" | "FileTransferResume" | "FileTransferComplete"
+        | "FileTransferCancel" | "FileTransferRequest" | "MonitorControl" | "P2PHandshake"
+        | "P2PResponse" | "TerminalOpen" | "TerminalInput" | "TerminalResize" | "TerminalClose"
+        | "TerminalScrollbackRequest" => {
+            // Pin the session so a technician cannot address another session's transfers,
+            // displays, terminal or direct connection
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let mut command = cmd.clone();
+            command["session_id"] = serde_json::Value::String(session_id.to_string());
+
+            // Drops are held to the device's policy and size limit before the agent hears of them
+            if cmd_type == "FileTransferStart" {
+                if let Err(e) = file_drop::admit_socket_transfer(device_manager, session_uuid, &command).await {
+                    debug!("Refused drop for session {}: {}", session_id, e);
+                    let refused = serde_json::json!({
+                        "type": "FileTransferComplete",
+                        "session_id": session_id,
+                        "transfer_id": command.get("transfer_id"),
+                        "success": false,
+                        "error": e.to_string(),
+                    });
+                    let _ = device_manager.send_to_session(session_uuid, Message::Text(refused.to_string())).await;
+                    return Ok(());
+                }
+            }
+
+            // Chunks go to envelope-speaking agents as binary
+            let binary = if cmd_type == "FileTransfer"
+                && device_manager.session_binary_protocol(session_uuid).await.is_some()
+            {
+                file_chunk_envelope(session_uuid, &command)
+            } else {
+                None
+            };
+            let message = match binary {
+                Some(envelope) => Message::Binary(envelope),
+                None => Message::Text(command.to_string()),
+            };
+            if let Err(e) = device_manager
+                .send_to_session_agent(session_uuid, message)
+                .await
+            {
+                warn!("Failed to forward {} for session {}: {}", cmd_type, session_id, e);
+            }
+        }
+        "keepalive" => {
+            // The technician is still there; the agent's own idle timer resets too
+            let session_uuid = Uuid::parse_str(session_id)?;
+            device_manager.idle_tracker.touch(session_uuid, Utc::now()).await;
+            let keepalive = serde_json::json!({ "type": "SessionKeepalive", "session_id": session_id });
+            if let Err(e) = device_manager
+                .send_to_session_agent(session_uuid, Message::Text(keepalive.to_string()))
+                .await
+            {
+                debug!("Failed to pass keepalive of session {} to agent: {}", session_id, e);
+            }
+        }
+        "ChatMessage" => {
+            let session_uuid = Uuid::parse_str(session_id)?;
+            chat::relay_message(device_manager, session_uuid, ChatParty::Technician, &cmd).await;
+        }
+        "ChatReceipt" => {
+            let session_uuid = Uuid::parse_str(session_id)?;
+            chat::relay_receipt(device_manager, session_uuid, ChatParty::Technician, &cmd).await;
         }
         "request_control" => {
-            // Technician is requesting control access
+            // Takes control if it is free, otherwise prompts the holder
             debug!("Session {} requesting control", session_id);
+            let session_uuid = Uuid::parse_str(session_id)?;
+            if let Err(e) = device_manager.request_control(session_uuid).await {
+                let denied = serde_json::json!({ "type": "ControlDenied", "reason": "not_allowed", "error": e });
+                let _ = device_manager.send_to_session(session_uuid, Message::Text(denied.to_string())).await;
+            }
+        }
+        "grant_control" | "deny_control" => {
+            // Holder's answer to a takeover prompt
+            let session_uuid = Uuid::parse_str(session_id)?;
+            let grant = cmd_type == "grant_control";
+            if let Err(e) = device_manager.answer_control_request(session_uuid, grant).await {
+                warn!("Failed to answer control request for session {}: {}", session_id, e);
+            }
         }
         "release_control" => {
-            // Technician is releasing control
+            // Technician is releasing control, or giving up on a request for it
             debug!("Session {} releasing control", session_id);
+            let session_uuid = Uuid::parse_str(session_id)?;
+            if let Err(e) = device_manager.release_control(session_uuid).await {
+                warn!("Failed to release control for session {}: {}", session_id, e);
+            }
         }
         _ => {
             debug!(
@@ -410,50 +1401,118 @@ async fn handle_session_command(
 // ============================================================================
 
 /// Manages relay connections and session routing.
-/// This is used for advanced routing when P2P connections fail.
-#[allow(dead_code)]
+/// Sessions are placed on registered relay nodes, or relayed through this
+/// server when there is none to use.
 pub struct RelayManager {
     /// Active sessions indexed by session ID
     sessions: Arc<RwLock<HashMap<String, SessionRoute>>>,
-    /// Available relay nodes
+    /// Registered relay nodes
     relay_nodes: Arc<RwLock<HashMap<Uuid, RelayNode>>>,
-    /// Load balancer for optimal routing
-    load_balancer: Arc<load_balancer::LoadBalancer>,
+    /// Picks the node for each new session
+    load_balancer: load_balancer::LoadBalancer,
+    policy: connection_broker::BrokerPolicy,
+    /// Secret shared with the relay nodes; brokering is off without one
+    node_key: Option<connection_broker::NodeKey>,
 }
 
 #[allow(dead_code)]
 impl RelayManager {
     pub fn new() -> Self {
+        Self::with_policy(connection_broker::BrokerPolicy::default())
+    }
+
+    pub fn with_policy(policy: connection_broker::BrokerPolicy) -> Self {
         Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
             relay_nodes: Arc::new(RwLock::new(HashMap::new())),
-            load_balancer: Arc::new(load_balancer::LoadBalancer::new()),
+            load_balancer: load_balancer::LoadBalancer::new(policy.strategy),
+            node_key: policy.node_secret.as_deref().map(connection_broker::NodeKey::new),
+            policy,
         }
     }
 
-    /// Create an optimal route for a session
+    pub fn strategy(&self) -> load_balancer::BalancingStrategy {
+        self.load_balancer.strategy()
+    }
+
+    /// Check the secret a relay node presented
+    pub fn authenticate_node(&self, presented: Option<&str>) -> Result<(), connection_broker::BrokerError> {
+        self.node_key
+            .as_ref()
+            .ok_or(connection_broker::BrokerError::Disabled)?
+            .authenticate(presented)
+    }
+
+    /// Create a route for a session, on the node the load balancer picks
     pub async fn create_route(
         &self,
         session_id: String,
         agent_id: String,
         technician_id: String,
-    ) -> Result<SessionRoute> {
-        // For now, create a direct relay route
-        // TODO: Implement P2P detection and optimal relay selection
-        let route = SessionRoute {
+        hints: &load_balancer::RouteHints,
+        now: DateTime<Utc>,
+    ) -> SessionRoute {
+        let mut route = SessionRoute {
             session_id: session_id.clone(),
             agent_id,
             technician_id,
             relay_node: None, // Direct through server
+            relay_address: None,
+            routing_token: None,
             connection_type: ConnectionType::RelayedTcp,
-            created_at: Utc::now(),
-            last_activity: Utc::now(),
+            created_at: now,
+            last_activity: now,
         };
 
+        if let Some(key) = &self.node_key {
+            let mut nodes = self.relay_nodes.write().await;
+            let active: Vec<RelayNode> = nodes
+                .values()
+                .filter(|node| node.status == connection_broker::NodeStatus::Active)
+                .cloned()
+                .collect();
+            let chosen = self.load_balancer.select(&active, hints).and_then(|id| nodes.get_mut(&id));
+            if let Some(node) = chosen {
+                let claims = connection_broker::route_claims(
+                    &session_id, node.id, &route.agent_id, &route.technician_id, now, self.policy.token_ttl_secs,
+                );
+                match key.mint(&claims) {
+                    Ok(token) => {
+                        // Counted until the node's next heartbeat reports the real load
+                        node.current_load += 1;
+                        route.relay_node = Some(node.id);
+                        route.relay_address = Some(node.address);
+                        route.routing_token = Some(token);
+                    }
+                    Err(e) => warn!("Failed to sign routing token for session {}: {}", session_id, e),
+                }
+            }
+        }
+
+        match route.relay_node {
+            Some(node_id) => info!("Session {} routed through relay node {}", session_id, node_id),
+            None => debug!("Session {} relayed through this server", session_id),
+        }
         let mut sessions = self.sessions.write().await;
         sessions.insert(session_id, route.clone());
 
-        Ok(route)
+        route
+    }
+
+    /// Record which transport a session's frames take
+    pub async fn set_connection_type(&self, session_id: &str, connection_type: ConnectionType) {
+        if let Some(route) = self.sessions.write().await.get_mut(session_id) {
+            route.connection_type = connection_type;
+            route.last_activity = Utc::now();
+        }
+    }
+
+    /// Check a routing token presented to node `node_id`
+    pub fn verify_route_token(&self, token: &str, node_id: Uuid) -> Result<connection_broker::RouteClaims, connection_broker::BrokerError> {
+        self.node_key
+            .as_ref()
+            .ok_or(connection_broker::BrokerError::Disabled)?
+            .verify(token, node_id)
     }
 
     /// Get session route
@@ -474,16 +1533,77 @@ impl RelayManager {
         sessions.values().cloned().collect()
     }
 
-    /// Register a relay node
-    pub async fn register_relay_node(&self, node: RelayNode) {
+    /// Register a relay node, or refresh one registering again
+    pub async fn register_relay_node(&self, registration: connection_broker::NodeRegistration, now: DateTime<Utc>) -> RelayNode {
+        let mut nodes = self.relay_nodes.write().await;
+        let previous = nodes.get(&registration.id);
+        let node = RelayNode {
+            id: registration.id,
+            address: registration.address,
+            region: registration.region,
+            capacity: registration.capacity,
+            current_load: previous.map_or(0, |node| node.current_load),
+            health_score: previous.map_or(1.0, |node| node.health_score),
+            last_heartbeat: now,
+            features: registration.features,
+            status: connection_broker::NodeStatus::Active,
+        };
+        nodes.insert(node.id, node.clone());
+        node
+    }
+
+    /// Record a node's load and health, taking it out of draining
+    pub async fn heartbeat(
+        &self,
+        heartbeat: connection_broker::NodeHeartbeat,
+        now: DateTime<Utc>,
+    ) -> Result<RelayNode, connection_broker::BrokerError> {
+        let mut nodes = self.relay_nodes.write().await;
+        let node = nodes
+            .get_mut(&heartbeat.id)
+            .ok_or(connection_broker::BrokerError::UnknownNode(heartbeat.id))?;
+        if node.status == connection_broker::NodeStatus::Draining {
+            info!("Relay node {} is heartbeating again", node.id);
+        }
+        node.current_load = heartbeat.current_load;
+        node.health_score = heartbeat.health_score.clamp(0.0, 1.0);
+        if let Some(capacity) = heartbeat.capacity.filter(|capacity| *capacity > 0) {
+            node.capacity = capacity;
+        }
+        node.last_heartbeat = now;
+        node.status = connection_broker::NodeStatus::Active;
+        Ok(node.clone())
+    }
+
+    /// Drain nodes that missed heartbeats and evict the ones silent for
+    /// longer, returning the evicted nodes
+    pub async fn sweep_relay_nodes(&self, now: DateTime<Utc>) -> Vec<Uuid> {
+        let drain_after = chrono::Duration::seconds(self.policy.drain_after_secs as i64);
+        let evict_after = chrono::Duration::seconds(self.policy.evict_after_secs as i64);
+        let mut evicted = Vec::new();
         let mut nodes = self.relay_nodes.write().await;
-        nodes.insert(node.id, node);
+        nodes.retain(|id, node| {
+            let silent = now - node.last_heartbeat;
+            if silent > evict_after {
+                warn!("Evicting relay node {}: no heartbeat for {}s", id, silent.num_seconds());
+                evicted.push(*id);
+                return false;
+            }
+            if silent > drain_after && node.status == connection_broker::NodeStatus::Active {
+                warn!("Draining relay node {}: no heartbeat for {}s", id, silent.num_seconds());
+                node.status = connection_broker::NodeStatus::Draining;
+            }
+            true
+        });
+        evicted
     }
 
-    /// Get relay node stats
-    pub async fn get_relay_stats(&self) -> HashMap<Uuid, RelayNode> {
+    /// Registered relay nodes, ordered by ID
+    pub async fn list_relay_nodes(&self) -> Vec<RelayNode> {
         let nodes = self.relay_nodes.read().await;
-        nodes.clone()
+        let mut nodes: Vec<RelayNode> = nodes.values().cloned().collect();
+        nodes.sort_by_key(|node| node.id);
+        nodes
     }
 }
 
@@ -492,3 +1612,51 @@ impl Default for RelayManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_manager::{DeviceRegistration, SessionRequest};
+    use crate::models::SessionType;
+
+    #[tokio::test]
+    async fn test_agent_errors_reach_the_session() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-01".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = device_manager.register_device(registration, outbound::channel().0).await.unwrap();
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        let request = SessionRequest { agent_id, session_type: SessionType::Control, user_id: Uuid::new_v4(), capabilities: None, bandwidth_kbps: None, compress_frames: false, ticket_ref: None };
+        let session_id = device_manager.create_session(request, viewer_tx).await.unwrap();
+        while tokio::time::timeout(Duration::from_millis(50), viewer_rx.recv()).await.is_ok() {}
+
+        let error = serde_json::json!({
+            "type": "Error",
+            "code": 404,
+            "message": "Input event rejected",
+            "session_id": session_id,
+        });
+        handle_agent_command(&device_manager, &agent_id.to_string(), error.clone()).await.unwrap();
+        let Ok(Some(Message::Text(text))) = tokio::time::timeout(Duration::from_millis(200), viewer_rx.recv()).await else {
+            panic!("the error never reached the viewer");
+        };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap(), error);
+
+        // Errors about no session, or another agent's, stay with the relay
+        let foreign = serde_json::json!({ "type": "Error", "code": 404, "message": "Session not found", "session_id": Uuid::new_v4() });
+        handle_agent_command(&device_manager, &agent_id.to_string(), foreign).await.unwrap();
+        let general = serde_json::json!({ "type": "Error", "code": 500, "message": "Capture failed" });
+        handle_agent_command(&device_manager, &agent_id.to_string(), general).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), viewer_rx.recv()).await.is_err());
+    }
+}
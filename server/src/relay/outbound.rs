@@ -0,0 +1,581 @@
+//! Bounded outbound queues for relay WebSocket writers
+//!
+//! Each socket gets one queue per message priority. Video frames and other
+//! low-value traffic drop their oldest entry when full, so a slow viewer only
+//! loses frames; critical and high-priority messages are never dropped. A full
+//! control queue is either reported to the caller or, with
+//! [`OutboundSender::send_wait`], waited out. The writer task closes
+//! connections whose queues stay saturated without progress.
+
+use axum::extract::ws::Message;
+use futures_util::{Sink, SinkExt};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use super::protocol::{Envelope, MessageKind};
+use super::MessagePriority;
+
+/// Number of priority classes, indexed by `MessagePriority as usize`
+const CLASSES: usize = 4;
+
+/// How often the writer checks for a stalled queue
+const SATURATION_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Queue sizing and overflow limits for one connection
+#[derive(Debug, Clone)]
+pub struct OutboundConfig {
+    /// Capacity per priority class, indexed by `MessagePriority as usize`
+    pub capacities: [usize; CLASSES],
+    /// How long a queue may stay full without the writer making progress
+    pub saturation_timeout: Duration,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            // Critical, High, Normal (video), Low
+            capacities: [64, 256, 8, 32],
+            saturation_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Reason a message could not be queued
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueError {
+    /// The writer has gone away
+    Closed,
+    /// A never-drop class is at capacity
+    Full(MessagePriority),
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Closed => write!(f, "connection closed"),
+            QueueError::Full(priority) => write!(f, "{:?} queue is full", priority),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Point-in-time queue metrics for a connection
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OutboundStats {
+    /// Messages waiting per class (critical, high, normal, low)
+    pub depth: [usize; CLASSES],
+    /// Payload bytes currently queued
+    pub queued_bytes: usize,
+    /// Messages dropped by the overflow policy per class
+    pub dropped: [u64; CLASSES],
+    /// Never-drop messages refused because their class was full
+    pub rejected: u64,
+    /// Never-drop messages that had to wait for room
+    pub waited: u64,
+    /// Messages handed to the socket
+    pub sent: u64,
+}
+
+struct QueueState {
+    queues: [VecDeque<Message>; CLASSES],
+    stats: OutboundStats,
+    /// When the queue last became full
+    full_since: Option<Instant>,
+    /// When the writer last took a message
+    last_progress: Instant,
+    closed: bool,
+}
+
+struct Shared {
+    config: OutboundConfig,
+    senders: AtomicUsize,
+    state: Mutex<QueueState>,
+    notify: Notify,
+    /// Woken whenever the writer takes a message or the queue closes
+    space: Notify,
+}
+
+/// Sending half of a connection's outbound queue
+pub struct OutboundSender {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of a connection's outbound queue, owned by the writer task
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+impl Clone for OutboundSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for OutboundSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // Wake the writer so it sees the queue has no producers left
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+impl std::fmt::Debug for OutboundSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundSender").field("stats", &self.stats()).finish()
+    }
+}
+
+/// Create an outbound queue with the default limits
+pub fn channel() -> (OutboundSender, OutboundReceiver) {
+    channel_with_config(OutboundConfig::default())
+}
+
+/// Create an outbound queue with explicit limits
+pub fn channel_with_config(config: OutboundConfig) -> (OutboundSender, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        config,
+        senders: AtomicUsize::new(1),
+        state: Mutex::new(QueueState {
+            queues: Default::default(),
+            stats: OutboundStats::default(),
+            full_since: None,
+            last_progress: Instant::now(),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        space: Notify::new(),
+    });
+
+    (OutboundSender { shared: shared.clone() }, OutboundReceiver { shared })
+}
+
+/// Default priority for a message: control traffic is critical, file chunks and
+/// input batches are high, and other binary payloads are video
+pub fn classify(message: &Message) -> MessagePriority {
+    match message {
+        // File chunks must arrive whole; only frames are expendable
+        Message::Binary(data) => match Envelope::decode(data).map(|envelope| envelope.kind) {
+            Ok(MessageKind::FileChunk | MessageKind::InputBatch | MessageKind::TerminalData) => {
+                MessagePriority::High
+            }
+            // Compressed JSON is control traffic all the same
+            Ok(MessageKind::ControlText) => MessagePriority::Critical,
+            _ => MessagePriority::Normal,
+        },
+        Message::Text(_) | Message::Ping(_) | Message::Pong(_) | Message::Close(_) => MessagePriority::Critical,
+    }
+}
+
+fn message_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(_) => 0,
+    }
+}
+
+fn is_droppable(priority: &MessagePriority) -> bool {
+    matches!(priority, MessagePriority::Normal | MessagePriority::Low)
+}
+
+impl Shared {
+    fn is_full(&self, state: &QueueState) -> bool {
+        state.queues.iter()
+            .zip(self.config.capacities)
+            .any(|(queue, capacity)| queue.len() >= capacity)
+    }
+
+    fn update_saturation(&self, state: &mut QueueState) {
+        if !self.is_full(state) {
+            state.full_since = None;
+        } else if state.full_since.is_none() {
+            state.full_since = Some(Instant::now());
+        }
+    }
+}
+
+impl OutboundSender {
+    /// Queue a message using its default priority
+    pub fn send(&self, message: Message) -> Result<(), QueueError> {
+        let priority = classify(&message);
+        self.send_with_priority(message, priority)
+    }
+
+    /// Queue a message, applying the overflow policy of its priority class
+    pub fn send_with_priority(&self, message: Message, priority: MessagePriority) -> Result<(), QueueError> {
+        self.push(message, priority, true).map_err(|(error, _)| error)
+    }
+
+    /// Queue a message, waiting for room if its class is full and never
+    /// drops. Fails only once the writer has gone away.
+    pub async fn send_wait(&self, mut message: Message, priority: MessagePriority) -> Result<(), QueueError> {
+        let mut waited = false;
+        loop {
+            // Registered before trying, so room made in between isn't missed
+            let space = self.shared.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            match self.push(message, priority.clone(), false) {
+                Ok(()) => return Ok(()),
+                Err((QueueError::Full(_), returned)) => {
+                    if !waited {
+                        waited = true;
+                        self.shared.state.lock().unwrap().stats.waited += 1;
+                    }
+                    message = returned;
+                    space.await;
+                }
+                Err((error, _)) => return Err(error),
+            }
+        }
+    }
+
+    /// Queue a message, handing it back if it can't be queued. A full
+    /// never-drop class counts as a rejection when `reject` is set.
+    fn push(&self, message: Message, priority: MessagePriority, reject: bool) -> Result<(), (QueueError, Message)> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.closed {
+            return Err((QueueError::Closed, message));
+        }
+
+        let class = priority.clone() as usize;
+        let capacity = shared.config.capacities[class];
+
+        if state.queues[class].len() >= capacity {
+            if !is_droppable(&priority) {
+                if reject {
+                    state.stats.rejected += 1;
+                }
+                shared.update_saturation(&mut state);
+                return Err((QueueError::Full(priority), message));
+            }
+            if let Some(oldest) = state.queues[class].pop_front() {
+                state.stats.queued_bytes -= message_len(&oldest);
+                state.stats.dropped[class] += 1;
+                debug!("Dropped oldest {:?} message from saturated outbound queue", priority);
+            }
+        }
+
+        state.stats.queued_bytes += message_len(&message);
+        state.queues[class].push_back(message);
+        state.stats.depth[class] = state.queues[class].len();
+        shared.update_saturation(&mut state);
+        drop(state);
+
+        shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Current queue metrics
+    pub fn stats(&self) -> OutboundStats {
+        self.shared.state.lock().unwrap().stats.clone()
+    }
+
+    /// Whether the writer has gone away
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+}
+
+impl OutboundReceiver {
+    /// Take the next message, highest priority first.
+    ///
+    /// Returns `None` once every sender is gone and the queue is drained.
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.try_recv() {
+                return Some(message);
+            }
+            if self.shared.senders.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    fn try_recv(&self) -> Option<Message> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+
+        let class = state.queues.iter().position(|queue| !queue.is_empty())?;
+        let message = state.queues[class].pop_front()?;
+        state.stats.depth[class] = state.queues[class].len();
+        state.stats.queued_bytes -= message_len(&message);
+        state.stats.sent += 1;
+        state.last_progress = Instant::now();
+        shared.update_saturation(&mut state);
+        drop(state);
+
+        shared.space.notify_waiters();
+        Some(message)
+    }
+
+    /// How long the queue has been full without the writer taking a message
+    pub fn saturated_for(&self) -> Duration {
+        let state = self.shared.state.lock().unwrap();
+        match state.full_since {
+            Some(since) => since.max(state.last_progress).elapsed(),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Current queue metrics
+    pub fn stats(&self) -> OutboundStats {
+        self.shared.state.lock().unwrap().stats.clone()
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.queues.iter_mut().for_each(VecDeque::clear);
+        state.stats.depth = [0; CLASSES];
+        state.stats.queued_bytes = 0;
+        drop(state);
+
+        // Senders waiting for room learn the connection is gone
+        self.shared.space.notify_waiters();
+    }
+}
+
+/// Forward queued messages to a socket until it fails, a close frame is sent,
+/// every sender is gone, or the queue stays saturated for longer than the
+/// configured timeout.
+pub async fn run_writer<S>(mut sink: S, rx: OutboundReceiver, label: &str)
+where
+    S: Sink<Message> + Unpin,
+{
+    let timeout = rx.shared.config.saturation_timeout;
+
+    let forward = async {
+        while let Some(message) = rx.recv().await {
+            // A queued close frame ends the connection once it is delivered
+            let closing = matches!(message, Message::Close(_));
+            if sink.send(message).await.is_err() || closing {
+                break;
+            }
+        }
+    };
+
+    let watchdog = async {
+        loop {
+            tokio::time::sleep(SATURATION_CHECK_INTERVAL.min(timeout)).await;
+            if rx.saturated_for() >= timeout {
+                warn!("Closing {}: outbound queue saturated for {:?} ({:?})", label, timeout, rx.stats());
+                break;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = forward => {}
+        _ = watchdog => {}
+    }
+}
+
+/// What tests see of a connection's queue
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// JSON messages queued on `rx`, until none comes for 50ms
+    pub async fn texts(rx: &OutboundReceiver) -> Vec<serde_json::Value> {
+        let mut texts = Vec::new();
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(50), rx.recv()).await {
+            if let Message::Text(text) = message {
+                texts.push(serde_json::from_str(&text).unwrap());
+            }
+        }
+        texts
+    }
+
+    /// The queued JSON messages of type `kind`
+    pub async fn texts_of(rx: &OutboundReceiver, kind: &str) -> Vec<serde_json::Value> {
+        texts(rx).await.into_iter().filter(|text| text["type"] == kind).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Socket that never accepts a message, like a peer that stopped reading
+    struct StalledSink;
+
+    impl Sink<Message> for StalledSink {
+        type Error = ();
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: Message) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_memory_stays_flat_with_stalled_consumer() {
+        let (tx, _rx) = channel();
+        let frame = vec![0u8; 64 * 1024];
+
+        for _ in 0..10_000 {
+            tx.send(Message::Binary(frame.clone())).unwrap();
+        }
+
+        let stats = tx.stats();
+        let capacity = OutboundConfig::default().capacities[MessagePriority::Normal as usize];
+        assert_eq!(stats.depth[MessagePriority::Normal as usize], capacity);
+        assert_eq!(stats.queued_bytes, capacity * frame.len());
+        assert_eq!(stats.dropped[MessagePriority::Normal as usize], 10_000 - capacity as u64);
+    }
+
+    #[tokio::test]
+    async fn test_control_messages_overtake_video() {
+        let (tx, rx) = channel();
+        for i in 0..100u8 {
+            tx.send(Message::Binary(vec![i])).unwrap();
+        }
+        tx.send(Message::Text("{\"type\":\"SessionEnd\"}".to_string())).unwrap();
+
+        assert!(matches!(rx.recv().await, Some(Message::Text(_))));
+        // Only the newest frames survived the overflow
+        assert!(matches!(rx.recv().await, Some(Message::Binary(data)) if data == vec![92]));
+    }
+
+    #[test]
+    fn test_critical_messages_are_never_dropped() {
+        let config = OutboundConfig { capacities: [2, 2, 2, 2], ..Default::default() };
+        let (tx, _rx) = channel_with_config(config);
+
+        tx.send(Message::Text("a".to_string())).unwrap();
+        tx.send(Message::Text("b".to_string())).unwrap();
+        assert_eq!(
+            tx.send(Message::Text("c".to_string())),
+            Err(QueueError::Full(MessagePriority::Critical))
+        );
+
+        let stats = tx.stats();
+        assert_eq!(stats.depth[MessagePriority::Critical as usize], 2);
+        assert_eq!(stats.dropped[MessagePriority::Critical as usize], 0);
+        assert_eq!(stats.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_flood_keeps_priority_order() {
+        let (tx, rx) = channel();
+        for i in 0..1000u32 {
+            tx.send(Message::Binary(i.to_be_bytes().to_vec())).unwrap();
+        }
+        tx.send_with_priority(Message::Text("low".to_string()), MessagePriority::Low).unwrap();
+        tx.send_with_priority(Message::Text("input".to_string()), MessagePriority::High).unwrap();
+        tx.send(Message::Text("critical".to_string())).unwrap();
+
+        let mut received = Vec::new();
+        while let Some(message) = tokio::time::timeout(Duration::from_millis(10), rx.recv()).await.ok().flatten() {
+            received.push(message);
+        }
+
+        assert_eq!(received[0], Message::Text("critical".to_string()));
+        assert_eq!(received[1], Message::Text("input".to_string()));
+        // The newest frames survive, in order, ahead of low-priority traffic
+        let capacity = OutboundConfig::default().capacities[MessagePriority::Normal as usize];
+        let frames: Vec<Message> = (1000 - capacity as u32..1000)
+            .map(|i| Message::Binary(i.to_be_bytes().to_vec()))
+            .collect();
+        assert_eq!(received[2..2 + capacity], frames[..]);
+        assert_eq!(received[2 + capacity], Message::Text("low".to_string()));
+        assert_eq!(received.len(), 3 + capacity);
+
+        let stats = rx.stats();
+        assert_eq!(stats.dropped, [0, 0, 1000 - capacity as u64, 0]);
+        assert_eq!(stats.sent, 3 + capacity as u64);
+    }
+
+    #[tokio::test]
+    async fn test_send_wait_applies_backpressure() {
+        let config = OutboundConfig { capacities: [1, 1, 1, 1], ..Default::default() };
+        let (tx, rx) = channel_with_config(config);
+        tx.send(Message::Text("first".to_string())).unwrap();
+
+        let waiting = {
+            let tx = tx.clone();
+            tokio::spawn(async move { tx.send_wait(Message::Text("second".to_string()), MessagePriority::Critical).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished(), "a full critical queue should hold the sender back");
+
+        assert_eq!(rx.recv().await, Some(Message::Text("first".to_string())));
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap().unwrap();
+        assert_eq!(rx.recv().await, Some(Message::Text("second".to_string())));
+
+        let stats = tx.stats();
+        assert_eq!(stats.waited, 1);
+        assert_eq!(stats.rejected, 0);
+        assert_eq!(stats.dropped[MessagePriority::Critical as usize], 0);
+
+        // A sender still waiting when the connection goes away is told so
+        tx.send(Message::Text("third".to_string())).unwrap();
+        let waiting = {
+            let tx = tx.clone();
+            tokio::spawn(async move { tx.send_wait(Message::Text("fourth".to_string()), MessagePriority::Critical).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(rx);
+        let result = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(result, Err(QueueError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_writer_closes_saturated_connection() {
+        let config = OutboundConfig {
+            saturation_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (tx, rx) = channel_with_config(config);
+
+        let writer = tokio::spawn(async move { run_writer(StalledSink, rx, "test connection").await });
+
+        // Keep frames flowing like a live agent until the writer gives up
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !writer.is_finished() {
+            assert!(Instant::now() < deadline, "writer should give up on a stalled socket");
+            let _ = tx.send(Message::Binary(vec![0; 1024]));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(Message::Text("late".to_string())), Err(QueueError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_writer_stops_when_senders_are_gone() {
+        let (tx, rx) = channel();
+        tx.send(Message::Text("bye".to_string())).unwrap();
+        drop(tx);
+
+        tokio::time::timeout(Duration::from_secs(5), run_writer(futures_util::sink::drain(), rx, "test connection"))
+            .await
+            .expect("writer should stop once senders are dropped");
+    }
+}
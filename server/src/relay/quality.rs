@@ -0,0 +1,402 @@
+//! Per-session connection quality
+//!
+//! The relay probes the agent behind every active session with
+//! `LatencyProbe` messages and times the echoes; the agent does the same in
+//! the other direction and adds its capture pipeline's numbers in a
+//! `QualityReport`. The monitor keeps the latest of both per session, grades
+//! them for the viewer's connection badge, and serves them to the API,
+//! along with how much of the session's input was coalesced.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::compression::CompressionReport;
+use super::input_coalescer::InputCounters;
+
+/// How often the relay probes each session's agent
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Unanswered probes count as lost after this long
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probe outcomes loss is computed over
+const LOSS_WINDOW: usize = 30;
+
+/// Weight of the newest sample in the smoothed round-trip time (RFC 6298)
+const RTT_WEIGHT: f64 = 0.125;
+
+/// Gain of the jitter estimate (RFC 3550)
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Round-trip time, jitter and loss of one link
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkQuality {
+    /// Smoothed round-trip time
+    pub rtt_ms: f64,
+    /// Mean deviation between successive round trips
+    pub jitter_ms: f64,
+    /// Share of recent probes that went unanswered
+    pub loss_percent: f64,
+    /// Probes answered or given up on so far
+    #[serde(default)]
+    pub samples: u64,
+}
+
+/// Tracks outstanding probes and the measurements their echoes produce
+#[derive(Debug, Default)]
+pub struct ProbeTracker {
+    next_sequence: u32,
+    outstanding: HashMap<u32, Instant>,
+    /// Recent outcomes, true if answered
+    outcomes: VecDeque<bool>,
+    smoothed_rtt: Option<f64>,
+    last_rtt: Option<f64>,
+    jitter: f64,
+    samples: u64,
+}
+
+impl ProbeTracker {
+    /// Register a probe sent at `now` and return its sequence number
+    pub fn next_probe(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.outstanding.insert(sequence, now);
+        sequence
+    }
+
+    /// Record the echo of probe `sequence`, returning its round-trip time.
+    ///
+    /// Echoes of unknown or already expired probes are ignored.
+    pub fn record_echo(&mut self, sequence: u32, now: Instant) -> Option<Duration> {
+        let sent = self.outstanding.remove(&sequence)?;
+        let rtt = now.saturating_duration_since(sent);
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+
+        if let Some(last) = self.last_rtt {
+            self.jitter += JITTER_GAIN * ((rtt_ms - last).abs() - self.jitter);
+        }
+        self.last_rtt = Some(rtt_ms);
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed) => smoothed + RTT_WEIGHT * (rtt_ms - smoothed),
+            None => rtt_ms,
+        });
+        self.push_outcome(true);
+        Some(rtt)
+    }
+
+    /// Count probes unanswered for longer than [`PROBE_TIMEOUT`] as lost
+    fn expire(&mut self, now: Instant) {
+        let before = self.outstanding.len();
+        self.outstanding.retain(|_, sent| now.saturating_duration_since(*sent) < PROBE_TIMEOUT);
+        for _ in self.outstanding.len()..before {
+            self.push_outcome(false);
+        }
+    }
+
+    fn push_outcome(&mut self, answered: bool) {
+        if self.outcomes.len() >= LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(answered);
+        self.samples += 1;
+    }
+
+    /// Current measurements
+    pub fn quality(&mut self, now: Instant) -> LinkQuality {
+        self.expire(now);
+        let lost = self.outcomes.iter().filter(|answered| !**answered).count();
+        LinkQuality {
+            rtt_ms: self.smoothed_rtt.unwrap_or(0.0),
+            jitter_ms: self.jitter,
+            loss_percent: if self.outcomes.is_empty() {
+                0.0
+            } else {
+                lost as f64 * 100.0 / self.outcomes.len() as f64
+            },
+            samples: self.samples,
+        }
+    }
+}
+
+/// What the agent reports of a session's connection
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentQualityReport {
+    /// Agent-measured link to the relay
+    pub link: LinkQuality,
+    /// Average capture and encode time per frame
+    pub encode_ms: f64,
+    pub capture_fps: u32,
+    pub bitrate_kbps: u32,
+    /// Messages waiting in the agent's relay queue
+    pub queue_depth: usize,
+    pub frames_dropped: u64,
+    /// "Direct" or "Relayed"
+    pub connection_type: String,
+    /// Name of the encoder in use
+    pub encoder: String,
+    /// Better encoders that failed their probe on the agent's machine
+    pub skipped_encoders: Vec<SkippedEncoder>,
+    /// Rate frames actually went out at, to compare against the cap
+    pub throughput_kbps: u32,
+    /// Bandwidth cap the agent enforces, if the session has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth_cap_kbps: Option<u32>,
+    /// Parts of the session the agent's watchdog is restarting:
+    /// "capture", "encoder" or "input"
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recovering: Vec<String>,
+    /// Codec agreed with the relay and how much it saved
+    pub compression: CompressionReport,
+}
+
+/// An encoder the agent passed over, and why
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SkippedEncoder {
+    pub encoder: String,
+    pub reason: String,
+}
+
+/// Badge shown in the session viewer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityGrade {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl QualityGrade {
+    fn of(link: &LinkQuality) -> Self {
+        if link.loss_percent >= 5.0 || link.rtt_ms >= 300.0 || link.jitter_ms >= 50.0 {
+            QualityGrade::Poor
+        } else if link.loss_percent >= 1.0 || link.rtt_ms >= 150.0 || link.jitter_ms >= 20.0 {
+            QualityGrade::Fair
+        } else {
+            QualityGrade::Good
+        }
+    }
+}
+
+/// Latest quality of a session, as served by the API and pushed to the viewer
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionQuality {
+    pub session_id: Uuid,
+    /// Relay-measured link to the agent
+    pub relay: LinkQuality,
+    /// Latest report from the agent, once one has arrived
+    pub agent: Option<AgentQualityReport>,
+    /// Worse of the two links
+    pub grade: QualityGrade,
+    /// Input events the viewer sent, and those forwarded after coalescing
+    pub input: InputCounters,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct SessionState {
+    tracker: ProbeTracker,
+    report: Option<AgentQualityReport>,
+    input: InputCounters,
+    updated_at: Option<DateTime<Utc>>,
+}
+
+impl SessionState {
+    fn snapshot(&mut self, session_id: Uuid) -> SessionQuality {
+        let relay = self.tracker.quality(Instant::now());
+        let mut grade = QualityGrade::of(&relay);
+        if let Some(report) = &self.report {
+            grade = grade.max(QualityGrade::of(&report.link));
+        }
+        SessionQuality {
+            session_id,
+            relay,
+            agent: self.report.clone(),
+            grade,
+            input: self.input,
+            updated_at: self.updated_at.unwrap_or_else(Utc::now),
+        }
+    }
+}
+
+/// Probe bookkeeping and latest reports for all active sessions
+#[derive(Default)]
+pub struct QualityMonitor {
+    sessions: RwLock<HashMap<Uuid, SessionState>>,
+}
+
+impl QualityMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a probe for a session and return its sequence number
+    pub async fn next_probe(&self, session_id: Uuid) -> u32 {
+        self.sessions.write().await
+            .entry(session_id)
+            .or_default()
+            .tracker
+            .next_probe(Instant::now())
+    }
+
+    /// Record the agent's echo of one of our probes
+    pub async fn record_echo(&self, session_id: Uuid, sequence: u32) -> Option<Duration> {
+        let mut sessions = self.sessions.write().await;
+        let state = sessions.get_mut(&session_id)?;
+        let rtt = state.tracker.record_echo(sequence, Instant::now());
+        if rtt.is_some() {
+            state.updated_at = Some(Utc::now());
+        }
+        rtt
+    }
+
+    /// Store the agent's latest report and return the combined view
+    pub async fn record_report(&self, session_id: Uuid, report: AgentQualityReport) -> SessionQuality {
+        let mut sessions = self.sessions.write().await;
+        let state = sessions.entry(session_id).or_default();
+        state.report = Some(report);
+        state.updated_at = Some(Utc::now());
+        state.snapshot(session_id)
+    }
+
+    /// Record the input counters of a session that is being probed
+    pub async fn record_input(&self, session_id: Uuid, counters: InputCounters) {
+        if let Some(state) = self.sessions.write().await.get_mut(&session_id) {
+            state.input = counters;
+        }
+    }
+
+    /// Latest quality of a session, if it has been measured
+    pub async fn quality(&self, session_id: Uuid) -> Option<SessionQuality> {
+        self.sessions.write().await
+            .get_mut(&session_id)
+            .map(|state| state.snapshot(session_id))
+    }
+
+    /// Forget a session that ended
+    pub async fn remove(&self, session_id: Uuid) {
+        self.sessions.write().await.remove(&session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_rtt_jitter_and_loss() {
+        let mut tracker = ProbeTracker::default();
+        let start = Instant::now();
+
+        let mut now = start;
+        for rtt in [100, 120, 80, 100] {
+            let sequence = tracker.next_probe(now);
+            assert_eq!(tracker.record_echo(sequence, now + ms(rtt)), Some(ms(rtt)));
+            now += ms(1000);
+        }
+        let lost = tracker.next_probe(now);
+
+        let quality = tracker.quality(now + ms(10));
+        // 100 -> 102.5 -> 99.69 -> 99.73
+        assert!((quality.rtt_ms - 99.73).abs() < 0.01, "{}", quality.rtt_ms);
+        // Deviations of 20, 40 and 20ms folded in at 1/16
+        let expected_jitter = [20.0, 40.0, 20.0].iter().fold(0.0, |jitter, deviation| jitter + (deviation - jitter) / 16.0);
+        assert!((quality.jitter_ms - expected_jitter).abs() < 1e-9);
+        assert_eq!(quality.loss_percent, 0.0, "the last probe is still in flight");
+
+        let quality = tracker.quality(now + PROBE_TIMEOUT);
+        assert_eq!(quality.loss_percent, 20.0);
+        assert_eq!(quality.samples, 5);
+        assert_eq!(tracker.record_echo(lost, now + PROBE_TIMEOUT), None, "late echoes are ignored");
+    }
+
+    #[test]
+    fn test_grades() {
+        let link = |rtt_ms, jitter_ms, loss_percent| LinkQuality { rtt_ms, jitter_ms, loss_percent, samples: 1 };
+        assert_eq!(QualityGrade::of(&link(40.0, 2.0, 0.0)), QualityGrade::Good);
+        assert_eq!(QualityGrade::of(&link(180.0, 2.0, 0.0)), QualityGrade::Fair);
+        assert_eq!(QualityGrade::of(&link(40.0, 25.0, 0.0)), QualityGrade::Fair);
+        assert_eq!(QualityGrade::of(&link(40.0, 2.0, 10.0)), QualityGrade::Poor);
+        assert_eq!(QualityGrade::of(&link(400.0, 2.0, 0.0)), QualityGrade::Poor);
+    }
+
+    #[tokio::test]
+    async fn test_monitor_combines_relay_and_agent_views() {
+        let monitor = QualityMonitor::new();
+        let session_id = Uuid::new_v4();
+        assert!(monitor.quality(session_id).await.is_none());
+
+        let sequence = monitor.next_probe(session_id).await;
+        assert!(monitor.record_echo(session_id, sequence).await.is_some());
+        assert!(monitor.record_echo(session_id, sequence).await.is_none(), "each probe is answered once");
+        assert!(monitor.record_echo(Uuid::new_v4(), 0).await.is_none());
+
+        let quality = monitor.quality(session_id).await.unwrap();
+        assert_eq!(quality.relay.samples, 1);
+        assert!(quality.agent.is_none());
+        assert_eq!(quality.grade, QualityGrade::Good);
+
+        // The agent's side of the link drags the grade down
+        let report = AgentQualityReport {
+            link: LinkQuality { rtt_ms: 90.0, jitter_ms: 4.0, loss_percent: 12.0, samples: 25 },
+            encode_ms: 9.5,
+            capture_fps: 24,
+            queue_depth: 3,
+            connection_type: "Relayed".to_string(),
+            ..AgentQualityReport::default()
+        };
+        let quality = monitor.record_report(session_id, report.clone()).await;
+        assert_eq!(quality.agent, Some(report));
+        assert_eq!(quality.grade, QualityGrade::Poor);
+
+        // Agents name their encoder and why better ones were passed over
+        let report: AgentQualityReport = serde_json::from_value(serde_json::json!({
+            "encoder": "Software JPEG Encoder (q=80)",
+            "skipped_encoders": [{ "encoder": "nvenc-h264", "reason": "NVENC not available on this system" }],
+        })).unwrap();
+        assert_eq!(report.skipped_encoders, [SkippedEncoder {
+            encoder: "nvenc-h264".to_string(),
+            reason: "NVENC not available on this system".to_string(),
+        }]);
+        // Older agents know nothing of bandwidth caps
+        assert_eq!((report.throughput_kbps, report.bandwidth_cap_kbps), (0, None));
+
+        let report: AgentQualityReport = serde_json::from_value(serde_json::json!({
+            "throughput_kbps": 480,
+            "bandwidth_cap_kbps": 500,
+        })).unwrap();
+        assert_eq!((report.throughput_kbps, report.bandwidth_cap_kbps), (480, Some(500)));
+        assert_eq!(report.compression.codec, None, "older agents compress nothing");
+
+        let report: AgentQualityReport = serde_json::from_value(serde_json::json!({
+            "compression": {
+                "codec": "zstd",
+                "text": { "original_bytes": 40000, "sent_bytes": 10000, "compressed_messages": 12, "ratio": 0.25 },
+                "frames_active": true,
+            },
+        })).unwrap();
+        assert_eq!(report.compression.codec.as_deref(), Some("zstd"));
+        assert_eq!(report.compression.text.ratio, 0.25);
+        assert_eq!(report.compression.frames.ratio, 1.0);
+        assert!(report.compression.frames_active);
+
+        let counters = InputCounters { events_in: 40, events_out: 3, batches: 2 };
+        monitor.record_input(session_id, counters).await;
+        assert_eq!(monitor.quality(session_id).await.unwrap().input, counters);
+
+        monitor.remove(session_id).await;
+        assert!(monitor.quality(session_id).await.is_none());
+        monitor.record_input(session_id, counters).await;
+        assert!(monitor.quality(session_id).await.is_none(), "ended sessions stay forgotten");
+    }
+}
@@ -1,562 +1,442 @@
-//! Rendezvous server for NAT traversal and peer discovery.
-//! Facilitates P2P connections between agents and technicians.
-#![allow(dead_code)]
+//! Rendezvous: reaching a device by its ID wherever it connects from
+//!
+//! Every connected agent holds a registration keyed by its stable agent ID:
+//! the connection it is on, the relay node it is reachable through (none
+//! while it is connected straight to this server), the public address the
+//! server saw it come from, from agents able to hole punch the UDP endpoint
+//! and NAT type they discovered, and from agents on Tailscale their tailnet
+//! address. A viewer on the same tailnet is pointed at that address first.
+//! Heartbeats keep the registration
+//! fresh; one left unrefreshed for [`REGISTRATION_TTL_SECS`] expires and the
+//! device resolves as offline.
+//!
+//! A device connecting again while its previous connection still looks alive
+//! replaces it: the old socket is closed with [`CLOSE_REPLACED`], and its
+//! cleanup leaves the new registration alone.
 
-use anyhow::Result;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
-};
-use tokio::{
-    net::UdpSocket,
-    sync::{broadcast, RwLock},
-    time::interval,
+    net::{IpAddr, SocketAddr},
 };
-use tracing::{debug, info, error};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RendezvousRequest {
-    pub session_id: String,
-    pub agent_id: String,
-    pub technician_id: String,
-    pub agent_endpoint: Option<SocketAddr>,
-    pub technician_endpoint: Option<SocketAddr>,
-    pub nat_type: NATType,
-    pub request_type: RendezvousType,
-    pub timestamp: u64,
-}
+use tokio::sync::RwLock;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::auth::jwt::error;
+use crate::organizations::Tenant;
+use crate::vpn_integration::TailnetAddress;
+use crate::AppState;
+
+/// How often registrations are checked for expiry
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Seconds without a heartbeat before a registration expires
+pub const REGISTRATION_TTL_SECS: i64 = 90;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RendezvousResponse {
-    pub session_id: String,
-    pub status: RendezvousStatus,
-    pub agent_endpoint: Option<SocketAddr>,
-    pub technician_endpoint: Option<SocketAddr>,
-    pub relay_endpoints: Vec<SocketAddr>,
-    pub hole_punch_instructions: Option<HolePunchInstructions>,
-    pub error_message: Option<String>,
+/// WebSocket close code: a newer connection with the same agent ID took over
+pub const CLOSE_REPLACED: u16 = 4409;
+
+/// How far ahead both ends are told to start punching, so the instructions
+/// reach the agent in time
+const HOLE_PUNCH_LEAD_MS: i64 = 2000;
+const HOLE_PUNCH_DURATION_MS: u32 = 5000;
+const HOLE_PUNCH_INTERVAL_MS: u32 = 100;
+
+/// NAT in front of an agent or viewer, as found by STUN
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NatType {
+    /// No NAT
+    Open,
+    FullCone,
+    RestrictedCone,
+    PortRestricted,
+    /// A new mapping per destination; hole punching can't predict it
+    Symmetric,
+    #[default]
+    Unknown,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum RendezvousType {
-    RegisterAgent,
-    RegisterTechnician,
-    RequestConnection,
-    TestConnectivity,
-    UpdateEndpoint,
+impl NatType {
+    /// Whether a peer behind this NAT can be reached by hole punching
+    pub fn allows_hole_punching(self) -> bool {
+        matches!(self, NatType::Open | NatType::FullCone | NatType::RestrictedCone | NatType::PortRestricted)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum RendezvousStatus {
-    Success,
-    Waiting,
-    Failed,
-    NATTraversalRequired,
-    RelayRequired,
+/// Where an agent can be reached, as of its current connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AgentEndpoints {
+    /// Address the agent's connection came from
+    pub public_ip: Option<IpAddr>,
+    /// Relay node the agent is connected through; none for this server
+    pub relay_node: Option<Uuid>,
+    /// UDP endpoint the agent found with STUN, if it can hole punch
+    pub p2p_endpoint: Option<SocketAddr>,
+    pub nat_type: NatType,
+    /// Tailnet the agent is on, with its address there
+    pub tailnet: Option<TailnetAddress>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum NATType {
-    Open,           // No NAT
-    FullCone,       // Full cone NAT
-    RestrictedCone, // Restricted cone NAT
-    PortRestricted, // Port restricted NAT
-    Symmetric,      // Symmetric NAT (hardest to traverse)
-    Unknown,        // Could not determine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub connection_id: Uuid,
+    pub endpoints: AgentEndpoints,
+    pub registered_at: DateTime<Utc>,
+    pub last_heartbeat: DateTime<Utc>,
+    /// Public address the device connected from before, when it was another
+    pub roamed_from: Option<IpAddr>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HolePunchInstructions {
-    pub start_time: u64,        // Synchronized start time
-    pub duration_ms: u32,       // How long to attempt
-    pub interval_ms: u32,       // Interval between attempts
-    pub target_endpoint: SocketAddr,
-    pub magic_bytes: Vec<u8>,   // Unique identifier for this session
+/// Outcome of registering a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registered {
+    pub connection_id: Uuid,
+    /// Earlier connection of the same device this one replaced
+    pub replaced: Option<Uuid>,
 }
 
-#[derive(Debug, Clone)]
-struct PendingConnection {
-    session_id: String,
-    agent_id: String,
-    technician_id: String,
-    agent_endpoint: Option<SocketAddr>,
-    technician_endpoint: Option<SocketAddr>,
-    agent_nat_type: NATType,
-    technician_nat_type: NATType,
-    created_at: Instant,
-    last_activity: Instant,
+/// The relay to reach a device through
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RelayTarget {
+    /// Relay node the device is connected through; none for this server
+    pub relay_node: Option<Uuid>,
+    pub address: Option<SocketAddr>,
 }
 
-/// Next-generation rendezvous service - 10x better than RustDesk
-pub struct RendezvousService {
-    /// UDP socket for rendezvous communication
-    socket: Arc<UdpSocket>,
-    
-    /// Pending connections waiting for pairing
-    pending_connections: Arc<RwLock<HashMap<String, PendingConnection>>>,
-    
-    /// Active agent registrations
-    agent_registry: Arc<RwLock<HashMap<String, AgentRegistration>>>,
-    
-    /// Broadcast channel for real-time updates
-    broadcast_tx: broadcast::Sender<RendezvousEvent>,
-    
-    /// NAT traversal statistics for optimization
-    nat_stats: Arc<RwLock<NATTraversalStats>>,
-    
-    /// STUN servers for NAT type detection
-    stun_servers: Vec<SocketAddr>,
+/// When and how both ends punch through their NATs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HolePunchInstructions {
+    /// Unix time in milliseconds both ends start sending
+    pub start_time: i64,
+    pub duration_ms: u32,
+    pub interval_ms: u32,
+    /// Sent in every probe so each end recognizes the other's
+    pub magic: String,
 }
 
-#[derive(Debug, Clone)]
-struct AgentRegistration {
-    agent_id: String,
-    public_endpoint: SocketAddr,
-    local_endpoint: Option<SocketAddr>,
-    nat_type: NATType,
-    last_heartbeat: Instant,
-    capabilities: Vec<String>,
+/// Where to try a direct connection before falling back to the relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct P2pCandidates {
+    pub endpoint: SocketAddr,
+    pub nat_type: NatType,
+    pub hole_punch: HolePunchInstructions,
 }
 
-#[derive(Debug, Clone)]
-pub enum RendezvousEvent {
-    AgentRegistered(String),
-    ConnectionRequested(String),
-    ConnectionEstablished(String),
-    ConnectionFailed(String, String),
-    NATTraversalStarted(String),
-    NATTraversalCompleted(String, bool),
+/// What a viewer learns about a device it asked for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Resolution {
+    pub device_id: Uuid,
+    pub alias: Option<String>,
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Connected from another public address than the time before
+    pub roamed: bool,
+    /// How to reach the device; unset while it is offline
+    pub relay: Option<RelayTarget>,
+    /// Set when both NATs allow trying a direct connection first
+    pub p2p: Option<P2pCandidates>,
+    /// The device's tailnet address, set when the viewer is on the same
+    /// tailnet. Viewers probe it before committing to it and fall back to
+    /// `p2p` and `relay` when it does not answer.
+    pub vpn: Option<TailnetAddress>,
 }
 
-#[derive(Debug, Clone)]
-struct NATTraversalStats {
-    total_attempts: u64,
-    successful_traversals: u64,
-    failed_traversals: u64,
-    traversal_by_nat_type: HashMap<String, (u64, u64)>, // (attempts, successes)
-    avg_traversal_time_ms: u32,
+/// Current registration of every connected device
+#[derive(Debug, Default)]
+pub struct RendezvousRegistry {
+    registrations: RwLock<HashMap<Uuid, Registration>>,
+    /// Public address of each device's last connection, to notice roaming
+    last_ips: RwLock<HashMap<Uuid, IpAddr>>,
 }
 
-impl RendezvousService {
-    pub async fn new(bind_addr: SocketAddr) -> Result<Self> {
-        let socket = UdpSocket::bind(bind_addr).await?;
-        let (broadcast_tx, _) = broadcast::channel(1000);
-        
-        info!("Rendezvous service listening on {}", bind_addr);
-        
-        Ok(Self {
-            socket: Arc::new(socket),
-            pending_connections: Arc::new(RwLock::new(HashMap::new())),
-            agent_registry: Arc::new(RwLock::new(HashMap::new())),
-            broadcast_tx,
-            nat_stats: Arc::new(RwLock::new(NATTraversalStats::new())),
-            stun_servers: Self::default_stun_servers(),
-        })
+impl RendezvousRegistry {
+    pub fn new() -> Self {
+        Self::default()
     }
-    
-    /// Start the rendezvous service
-    pub async fn start(self: Arc<Self>) -> Result<()> {
-        // Start cleanup task
-        let cleanup_service = Arc::clone(&self);
-        tokio::spawn(async move {
-            cleanup_service.cleanup_task().await;
-        });
-        
-        // Start heartbeat monitoring
-        let heartbeat_service = Arc::clone(&self);
-        tokio::spawn(async move {
-            heartbeat_service.heartbeat_monitor().await;
-        });
-        
-        // Main message handling loop
-        let mut buffer = vec![0u8; 65536];
-        loop {
-            match self.socket.recv_from(&mut buffer).await {
-                Ok((size, addr)) => {
-                    let data = &buffer[..size];
-                    if let Err(e) = self.handle_message(data, addr).await {
-                        error!("Error handling rendezvous message from {}: {}", addr, e);
-                    }
-                }
-                Err(e) => {
-                    error!("Error receiving UDP message: {}", e);
-                }
-            }
+
+    /// Register a new connection of `device_id`, replacing any earlier one
+    pub async fn register(&self, device_id: Uuid, endpoints: AgentEndpoints, now: DateTime<Utc>) -> Registered {
+        let previous_ip = self.last_ips.read().await.get(&device_id).copied();
+        let roamed_from = previous_ip.filter(|ip| endpoints.public_ip.is_some_and(|current| current != *ip));
+        if let Some(ip) = endpoints.public_ip {
+            self.last_ips.write().await.insert(device_id, ip);
         }
-    }
-    
-    async fn handle_message(&self, data: &[u8], from_addr: SocketAddr) -> Result<()> {
-        // Parse the rendezvous request
-        let request: RendezvousRequest = serde_json::from_slice(data)?;
-        debug!("Received rendezvous request: {:?} from {}", request.request_type, from_addr);
-        
-        let response = match request.request_type {
-            RendezvousType::RegisterAgent => {
-                self.handle_agent_registration(&request, from_addr).await?
-            }
-            RendezvousType::RegisterTechnician => {
-                self.handle_technician_registration(&request, from_addr).await?
-            }
-            RendezvousType::RequestConnection => {
-                self.handle_connection_request(&request, from_addr).await?
-            }
-            RendezvousType::TestConnectivity => {
-                self.handle_connectivity_test(&request, from_addr).await?
-            }
-            RendezvousType::UpdateEndpoint => {
-                self.handle_endpoint_update(&request, from_addr).await?
-            }
-        };
-        
-        // Send response
-        let response_data = serde_json::to_vec(&response)?;
-        self.socket.send_to(&response_data, from_addr).await?;
-        
-        Ok(())
-    }
-    
-    async fn handle_agent_registration(
-        &self,
-        request: &RendezvousRequest,
-        from_addr: SocketAddr,
-    ) -> Result<RendezvousResponse> {
-        info!("Registering agent: {} from {}", request.agent_id, from_addr);
-        
-        // Detect NAT type if not provided
-        let nat_type = if matches!(request.nat_type, NATType::Unknown) {
-            self.detect_nat_type(from_addr).await
-        } else {
-            request.nat_type.clone()
-        };
-        
-        // Register the agent
-        let registration = AgentRegistration {
-            agent_id: request.agent_id.clone(),
-            public_endpoint: from_addr,
-            local_endpoint: request.agent_endpoint,
-            nat_type: nat_type.clone(),
-            last_heartbeat: Instant::now(),
-            capabilities: vec![
-                "screen_capture".to_string(),
-                "input_control".to_string(),
-                "file_transfer".to_string(),
-            ],
+        if let Some(from) = roamed_from {
+            info!("Device {} roamed from {} to {:?}", device_id, from, endpoints.public_ip);
+        }
+
+        let registration = Registration {
+            connection_id: Uuid::new_v4(),
+            endpoints,
+            registered_at: now,
+            last_heartbeat: now,
+            roamed_from,
         };
-        
-        let mut registry = self.agent_registry.write().await;
-        registry.insert(request.agent_id.clone(), registration);
-        
-        // Broadcast agent registration event
-        let _ = self.broadcast_tx.send(RendezvousEvent::AgentRegistered(request.agent_id.clone()));
-        
-        Ok(RendezvousResponse {
-            session_id: request.session_id.clone(),
-            status: RendezvousStatus::Success,
-            agent_endpoint: Some(from_addr),
-            technician_endpoint: None,
-            relay_endpoints: self.get_relay_endpoints(&nat_type).await,
-            hole_punch_instructions: None,
-            error_message: None,
-        })
+        let connection_id = registration.connection_id;
+        let replaced = self.registrations.write().await
+            .insert(device_id, registration)
+            .map(|previous| previous.connection_id);
+        Registered { connection_id, replaced }
     }
-    
-    async fn handle_technician_registration(
-        &self,
-        request: &RendezvousRequest,
-        from_addr: SocketAddr,
-    ) -> Result<RendezvousResponse> {
-        info!("Registering technician: {} from {}", request.technician_id, from_addr);
-        
-        // Check if target agent is available
-        let registry = self.agent_registry.read().await;
-        if let Some(agent_reg) = registry.get(&request.agent_id) {
-            // Agent is available, can proceed with connection
-            Ok(RendezvousResponse {
-                session_id: request.session_id.clone(),
-                status: RendezvousStatus::Success,
-                agent_endpoint: Some(agent_reg.public_endpoint),
-                technician_endpoint: Some(from_addr),
-                relay_endpoints: self.get_relay_endpoints(&request.nat_type).await,
-                hole_punch_instructions: None,
-                error_message: None,
-            })
-        } else {
-            // Agent not available
-            Ok(RendezvousResponse {
-                session_id: request.session_id.clone(),
-                status: RendezvousStatus::Failed,
-                agent_endpoint: None,
-                technician_endpoint: Some(from_addr),
-                relay_endpoints: Vec::new(),
-                hole_punch_instructions: None,
-                error_message: Some("Target agent not available".to_string()),
-            })
+
+    /// Keep a device's registration from expiring
+    pub async fn refresh(&self, device_id: Uuid, now: DateTime<Utc>) -> bool {
+        match self.registrations.write().await.get_mut(&device_id) {
+            Some(registration) => {
+                registration.last_heartbeat = registration.last_heartbeat.max(now);
+                true
+            }
+            None => false,
         }
     }
-    
-    async fn handle_connection_request(
-        &self,
-        request: &RendezvousRequest,
-        from_addr: SocketAddr,
-    ) -> Result<RendezvousResponse> {
-        info!("Connection request for session: {}", request.session_id);
-        
-        // Check if we have both endpoints
-        let mut pending = self.pending_connections.write().await;
-        
-        if let Some(existing) = pending.get_mut(&request.session_id) {
-            // Complete the connection
-            existing.last_activity = Instant::now();
-            
-            // Determine if NAT traversal is needed
-            let need_traversal = self.needs_nat_traversal(&existing.agent_nat_type, &request.nat_type);
-            
-            if need_traversal {
-                // Generate hole punching instructions
-                let instructions = self.generate_hole_punch_instructions(
-                    &request.session_id,
-                    existing.agent_endpoint.unwrap_or(existing.agent_endpoint.unwrap()),
-                    from_addr,
-                ).await;
-                
-                let _ = self.broadcast_tx.send(RendezvousEvent::NATTraversalStarted(request.session_id.clone()));
-                
-                Ok(RendezvousResponse {
-                    session_id: request.session_id.clone(),
-                    status: RendezvousStatus::NATTraversalRequired,
-                    agent_endpoint: existing.agent_endpoint,
-                    technician_endpoint: Some(from_addr),
-                    relay_endpoints: self.get_relay_endpoints(&request.nat_type).await,
-                    hole_punch_instructions: Some(instructions),
-                    error_message: None,
-                })
-            } else {
-                // Direct connection possible
-                let _ = self.broadcast_tx.send(RendezvousEvent::ConnectionEstablished(request.session_id.clone()));
-                
-                Ok(RendezvousResponse {
-                    session_id: request.session_id.clone(),
-                    status: RendezvousStatus::Success,
-                    agent_endpoint: existing.agent_endpoint,
-                    technician_endpoint: Some(from_addr),
-                    relay_endpoints: Vec::new(),
-                    hole_punch_instructions: None,
-                    error_message: None,
-                })
+
+    /// Drop the registration of a closed connection, returning false when a
+    /// newer connection of the device already replaced it
+    pub async fn unregister(&self, device_id: Uuid, connection_id: Uuid) -> bool {
+        let mut registrations = self.registrations.write().await;
+        match registrations.get(&device_id) {
+            Some(registration) if registration.connection_id == connection_id => {
+                registrations.remove(&device_id);
+                true
             }
-        } else {
-            // First endpoint for this session
-            let connection = PendingConnection {
-                session_id: request.session_id.clone(),
-                agent_id: request.agent_id.clone(),
-                technician_id: request.technician_id.clone(),
-                agent_endpoint: request.agent_endpoint,
-                technician_endpoint: Some(from_addr),
-                agent_nat_type: NATType::Unknown,
-                technician_nat_type: request.nat_type.clone(),
-                created_at: Instant::now(),
-                last_activity: Instant::now(),
-            };
-            
-            pending.insert(request.session_id.clone(), connection);
-            
-            let _ = self.broadcast_tx.send(RendezvousEvent::ConnectionRequested(request.session_id.clone()));
-            
-            Ok(RendezvousResponse {
-                session_id: request.session_id.clone(),
-                status: RendezvousStatus::Waiting,
-                agent_endpoint: None,
-                technician_endpoint: Some(from_addr),
-                relay_endpoints: self.get_relay_endpoints(&request.nat_type).await,
-                hole_punch_instructions: None,
-                error_message: None,
-            })
+            Some(_) => false,
+            // Expired already; the connection was still the device's latest
+            None => true,
         }
     }
-    
-    async fn handle_connectivity_test(
-        &self,
-        request: &RendezvousRequest,
-        from_addr: SocketAddr,
-    ) -> Result<RendezvousResponse> {
-        debug!("Connectivity test from {}", from_addr);
-        
-        // Echo back with timing information
-        Ok(RendezvousResponse {
-            session_id: request.session_id.clone(),
-            status: RendezvousStatus::Success,
-            agent_endpoint: None,
-            technician_endpoint: Some(from_addr),
-            relay_endpoints: Vec::new(),
-            hole_punch_instructions: None,
-            error_message: None,
-        })
-    }
-    
-    async fn handle_endpoint_update(
-        &self,
-        request: &RendezvousRequest,
-        from_addr: SocketAddr,
-    ) -> Result<RendezvousResponse> {
-        debug!("Endpoint update from {}", from_addr);
-        
-        // Update agent registration if it exists
-        let mut registry = self.agent_registry.write().await;
-        if let Some(registration) = registry.get_mut(&request.agent_id) {
-            registration.public_endpoint = from_addr;
-            registration.last_heartbeat = Instant::now();
-        }
-        
-        Ok(RendezvousResponse {
-            session_id: request.session_id.clone(),
-            status: RendezvousStatus::Success,
-            agent_endpoint: Some(from_addr),
-            technician_endpoint: None,
-            relay_endpoints: Vec::new(),
-            hole_punch_instructions: None,
-            error_message: None,
-        })
+
+    pub async fn get(&self, device_id: Uuid) -> Option<Registration> {
+        self.registrations.read().await.get(&device_id).cloned()
     }
-    
-    async fn detect_nat_type(&self, _from_addr: SocketAddr) -> NATType {
-        // TODO: Implement proper NAT type detection using STUN
-        // This is 10x better than RustDesk because we actually detect the NAT type
-        // instead of just assuming
-        debug!("NAT type detection not yet implemented, defaulting to Unknown");
-        NATType::Unknown
+
+    /// Drop registrations nobody refreshed within the TTL, returning the devices
+    pub async fn expire(&self, now: DateTime<Utc>) -> Vec<Uuid> {
+        let ttl = Duration::seconds(REGISTRATION_TTL_SECS);
+        let mut expired = Vec::new();
+        self.registrations.write().await.retain(|device_id, registration| {
+            let live = now - registration.last_heartbeat <= ttl;
+            if !live {
+                debug!("Rendezvous registration of {} expired", device_id);
+                expired.push(*device_id);
+            }
+            live
+        });
+        expired
     }
-    
-    fn needs_nat_traversal(&self, agent_nat: &NATType, tech_nat: &NATType) -> bool {
-        match (agent_nat, tech_nat) {
-            (NATType::Open, NATType::Open) => false,
-            (NATType::Open, _) | (_, NATType::Open) => false,
-            (NATType::Symmetric, _) | (_, NATType::Symmetric) => true, // Always need relay for symmetric NAT
-            _ => true, // Conservative approach: assume traversal needed
-        }
+
+    /// Live registrations of agents on a tailnet, with their last heartbeat
+    pub async fn tailnet_members(&self, now: DateTime<Utc>) -> Vec<(Uuid, TailnetAddress, DateTime<Utc>)> {
+        let ttl = Duration::seconds(REGISTRATION_TTL_SECS);
+        self.registrations.read().await
+            .iter()
+            .filter(|(_, registration)| now - registration.last_heartbeat <= ttl)
+            .filter_map(|(device_id, registration)| {
+                let tailnet = registration.endpoints.tailnet.clone()?;
+                Some((*device_id, tailnet, registration.last_heartbeat))
+            })
+            .collect()
     }
-    
-    async fn generate_hole_punch_instructions(
+
+    /// How a viewer behind `viewer_nat`, and on `viewer_tailnet` if any,
+    /// reaches `device_id`, if it is registered
+    pub async fn resolve(
         &self,
-        session_id: &str,
-        agent_endpoint: SocketAddr,
-        tech_endpoint: SocketAddr,
-    ) -> HolePunchInstructions {
-        // Generate synchronized timing for hole punching
-        let start_time = chrono::Utc::now().timestamp_millis() as u64 + 2000; // 2 seconds from now
-        let magic_bytes = format!("GhostLink-{}", session_id).as_bytes().to_vec();
-        
-        HolePunchInstructions {
-            start_time,
-            duration_ms: 5000,     // 5 seconds of attempts
-            interval_ms: 100,      // Attempt every 100ms
-            target_endpoint: if agent_endpoint.ip() != tech_endpoint.ip() {
-                tech_endpoint // Cross-NAT case
-            } else {
-                agent_endpoint // Same-NAT case
+        device_id: Uuid,
+        viewer_nat: NatType,
+        viewer_tailnet: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Resolution {
+        let registration = self.get(device_id).await
+            .filter(|registration| now - registration.last_heartbeat <= Duration::seconds(REGISTRATION_TTL_SECS));
+        let Some(registration) = registration else {
+            return Resolution {
+                device_id,
+                alias: None,
+                online: false,
+                last_seen: None,
+                roamed: false,
+                relay: None,
+                p2p: None,
+                vpn: None,
+            };
+        };
+
+        let endpoints = &registration.endpoints;
+        // Two symmetric NATs can't meet; one open side can reach any NAT
+        let punchable = endpoints.nat_type.allows_hole_punching()
+            && (viewer_nat.allows_hole_punching() || viewer_nat == NatType::Unknown);
+        let p2p = endpoints.p2p_endpoint.filter(|_| punchable).map(|endpoint| P2pCandidates {
+            endpoint,
+            nat_type: endpoints.nat_type,
+            hole_punch: HolePunchInstructions {
+                start_time: now.timestamp_millis() + HOLE_PUNCH_LEAD_MS,
+                duration_ms: HOLE_PUNCH_DURATION_MS,
+                interval_ms: HOLE_PUNCH_INTERVAL_MS,
+                magic: format!("ghostlink-{}", Uuid::new_v4().simple()),
             },
-            magic_bytes,
+        });
+
+        // Both on the same overlay network: the tailnet address skips NATs
+        let vpn = endpoints.tailnet.clone()
+            .filter(|tailnet| viewer_tailnet.is_some_and(|viewer| viewer == tailnet.tailnet));
+
+        Resolution {
+            device_id,
+            alias: None,
+            online: true,
+            last_seen: Some(registration.last_heartbeat),
+            roamed: registration.roamed_from.is_some(),
+            relay: Some(RelayTarget { relay_node: endpoints.relay_node, address: None }),
+            p2p,
+            vpn,
         }
     }
-    
-    async fn get_relay_endpoints(&self, _nat_type: &NATType) -> Vec<SocketAddr> {
-        // TODO: Return actual relay server endpoints based on geographic location
-        // This is better than RustDesk because we consider NAT type and location
-        vec![
-            "relay1.ghostlink.com:8080".parse().unwrap(),
-            "relay2.ghostlink.com:8080".parse().unwrap(),
-        ]
+}
+
+/// Body of `POST /api/rendezvous/resolve`
+#[derive(Debug, Deserialize)]
+pub struct ResolveRequest {
+    /// Agent ID, or the alias technicians gave the device
+    pub device_id: String,
+    /// The viewer's NAT, when it ran STUN
+    #[serde(default)]
+    pub nat_type: NatType,
+    /// MagicDNS suffix of the viewer's tailnet, when it is on Tailscale
+    #[serde(default)]
+    pub tailnet: Option<String>,
+}
+
+/// Find out how to reach a device by its ID or alias
+pub async fn api_resolve_device(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Json(request): Json<ResolveRequest>,
+) -> Response {
+    match app_state.device_manager.resolve_device(tenant, &request.device_id, request.nat_type, request.tailnet.as_deref(), Utc::now()).await {
+        Some(resolution) => Json(resolution).into_response(),
+        None => error(StatusCode::NOT_FOUND, format!("Unknown device: {}", request.device_id)),
     }
-    
-    async fn cleanup_task(&self) {
-        let mut cleanup_interval = interval(Duration::from_secs(30));
-        
-        loop {
-            cleanup_interval.tick().await;
-            
-            let now = Instant::now();
-            let timeout = Duration::from_secs(300); // 5 minutes
-            
-            // Clean up stale pending connections
-            {
-                let mut pending = self.pending_connections.write().await;
-                pending.retain(|_, connection| {
-                    now.duration_since(connection.last_activity) < timeout
-                });
-            }
-            
-            // Clean up stale agent registrations
-            {
-                let mut registry = self.agent_registry.write().await;
-                registry.retain(|_, registration| {
-                    now.duration_since(registration.last_heartbeat) < timeout
-                });
-            }
-        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints(ip: &str) -> AgentEndpoints {
+        AgentEndpoints { public_ip: Some(ip.parse().unwrap()), ..AgentEndpoints::default() }
     }
-    
-    async fn heartbeat_monitor(&self) {
-        let mut heartbeat_interval = interval(Duration::from_secs(60));
-        
-        loop {
-            heartbeat_interval.tick().await;
-            
-            let registry = self.agent_registry.read().await;
-            let agent_count = registry.len();
-            let pending = self.pending_connections.read().await;
-            let pending_count = pending.len();
-            
-            info!("Rendezvous stats: {} agents, {} pending connections", agent_count, pending_count);
-        }
+
+    #[tokio::test]
+    async fn test_registrations_expire_without_heartbeats() {
+        let registry = RendezvousRegistry::new();
+        let device = Uuid::new_v4();
+        let start = Utc::now();
+        registry.register(device, endpoints("198.51.100.7"), start).await;
+
+        registry.refresh(device, start + Duration::seconds(60)).await;
+        assert!(registry.expire(start + Duration::seconds(120)).await.is_empty());
+        assert!(registry.resolve(device, NatType::Unknown, None, start + Duration::seconds(120)).await.online);
+
+        // Past the TTL it resolves as offline even before the sweep runs
+        let late = start + Duration::seconds(60 + REGISTRATION_TTL_SECS + 1);
+        assert!(!registry.resolve(device, NatType::Unknown, None, late).await.online);
+        assert_eq!(registry.expire(late).await, [device]);
+        assert!(registry.get(device).await.is_none());
+        assert!(!registry.refresh(device, late).await);
     }
-    
-    fn default_stun_servers() -> Vec<SocketAddr> {
-        vec![
-            "stun.l.google.com:19302".parse().unwrap(),
-            "stun1.l.google.com:19302".parse().unwrap(),
-            "stun2.l.google.com:19302".parse().unwrap(),
-        ]
+
+    #[tokio::test]
+    async fn test_second_connection_replaces_the_first() {
+        let registry = RendezvousRegistry::new();
+        let device = Uuid::new_v4();
+        let now = Utc::now();
+
+        let first = registry.register(device, endpoints("198.51.100.7"), now).await;
+        assert_eq!(first.replaced, None);
+        let second = registry.register(device, endpoints("198.51.100.7"), now).await;
+        assert_eq!(second.replaced, Some(first.connection_id));
+
+        // The zombie's cleanup must not unregister its replacement
+        assert!(!registry.unregister(device, first.connection_id).await);
+        assert_eq!(registry.get(device).await.unwrap().connection_id, second.connection_id);
+        assert!(registry.unregister(device, second.connection_id).await);
+        assert!(registry.get(device).await.is_none());
     }
-    
-    /// Get rendezvous statistics
-    pub async fn get_stats(&self) -> RendezvousStats {
-        let registry = self.agent_registry.read().await;
-        let pending = self.pending_connections.read().await;
-        let nat_stats = self.nat_stats.read().await;
-        
-        RendezvousStats {
-            active_agents: registry.len(),
-            pending_connections: pending.len(),
-            total_nat_traversal_attempts: nat_stats.total_attempts,
-            successful_nat_traversals: nat_stats.successful_traversals,
-            nat_traversal_success_rate: if nat_stats.total_attempts > 0 {
-                nat_stats.successful_traversals as f32 / nat_stats.total_attempts as f32
-            } else {
-                0.0
-            },
-        }
+
+    #[tokio::test]
+    async fn test_resolve_online_offline_and_roamed() {
+        let registry = RendezvousRegistry::new();
+        let now = Utc::now();
+
+        let offline = registry.resolve(Uuid::new_v4(), NatType::Unknown, None, now).await;
+        assert!(!offline.online);
+        assert_eq!((offline.relay, offline.p2p), (None, None));
+
+        // Through this server, or the relay node the agent is on
+        let device = Uuid::new_v4();
+        let node = Uuid::new_v4();
+        let at_office = AgentEndpoints { relay_node: Some(node), ..endpoints("198.51.100.7") };
+        let connection = registry.register(device, at_office, now).await;
+        let online = registry.resolve(device, NatType::Unknown, None, now).await;
+        assert!(online.online && !online.roamed);
+        assert_eq!(online.relay.unwrap().relay_node, Some(node));
+        assert_eq!(online.p2p, None);
+
+        // Reconnecting from another network is noticed
+        registry.unregister(device, connection.connection_id).await;
+        let at_home = AgentEndpoints {
+            p2p_endpoint: Some("203.0.113.9:40123".parse().unwrap()),
+            nat_type: NatType::PortRestricted,
+            ..endpoints("203.0.113.9")
+        };
+        registry.register(device, at_home, now).await;
+        let roamed = registry.resolve(device, NatType::FullCone, None, now).await;
+        assert!(roamed.roamed);
+        assert_eq!(registry.get(device).await.unwrap().roamed_from, Some("198.51.100.7".parse().unwrap()));
+        assert_eq!(roamed.relay.unwrap().relay_node, None);
+
+        // Candidates to punch through to, unless the viewer's NAT rules it out
+        let p2p = roamed.p2p.unwrap();
+        assert_eq!(p2p.endpoint, "203.0.113.9:40123".parse().unwrap());
+        assert!(p2p.hole_punch.start_time > now.timestamp_millis());
+        assert_eq!(registry.resolve(device, NatType::Symmetric, None, now).await.p2p, None);
     }
-}
 
-impl NATTraversalStats {
-    fn new() -> Self {
-        Self {
-            total_attempts: 0,
-            successful_traversals: 0,
-            failed_traversals: 0,
-            traversal_by_nat_type: HashMap::new(),
-            avg_traversal_time_ms: 0,
-        }
+    #[tokio::test]
+    async fn test_same_tailnet_prefers_the_tailnet_address() {
+        let registry = RendezvousRegistry::new();
+        let now = Utc::now();
+        let device = Uuid::new_v4();
+        let tailnet = TailnetAddress { tailnet: "tail1234.ts.net".to_string(), address: "100.64.0.7".parse().unwrap() };
+        let on_tailnet = AgentEndpoints {
+            p2p_endpoint: Some("203.0.113.9:40123".parse().unwrap()),
+            nat_type: NatType::FullCone,
+            tailnet: Some(tailnet.clone()),
+            ..endpoints("203.0.113.9")
+        };
+        registry.register(device, on_tailnet, now).await;
+
+        let same = registry.resolve(device, NatType::FullCone, Some("tail1234.ts.net"), now).await;
+        assert_eq!(same.vpn, Some(tailnet.clone()));
+        // Hole punching and the relay remain as fallbacks
+        assert!(same.p2p.is_some() && same.relay.is_some());
+
+        assert_eq!(registry.resolve(device, NatType::FullCone, Some("tail9999.ts.net"), now).await.vpn, None);
+        assert_eq!(registry.resolve(device, NatType::FullCone, None, now).await.vpn, None);
+        assert_eq!(registry.tailnet_members(now).await, [(device, tailnet, now)]);
+
+        let elsewhere = Uuid::new_v4();
+        registry.register(elsewhere, endpoints("198.51.100.7"), now).await;
+        assert_eq!(registry.resolve(elsewhere, NatType::FullCone, Some("tail1234.ts.net"), now).await.vpn, None);
+        assert_eq!(registry.tailnet_members(now).await.len(), 1);
     }
 }
-
-#[derive(Debug, Clone, Serialize)]
-pub struct RendezvousStats {
-    pub active_agents: usize,
-    pub pending_connections: usize,
-    pub total_nat_traversal_attempts: u64,
-    pub successful_nat_traversals: u64,
-    pub nat_traversal_success_rate: f32,
-}
\ No newline at end of file
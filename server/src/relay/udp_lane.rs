@@ -0,0 +1,388 @@
+//! UDP lane for agents' video frames
+//!
+//! Sessions created with `ConnectionType::Hybrid` offer the agent an
+//! association on the relay's UDP port: a token sent over the WebSocket,
+//! which the agent presents in a `Hello` from the socket it streams from.
+//! Frames arriving on the lane are reassembled (see `datagram`) and take the
+//! same path as binary messages from the agent's WebSocket; control and
+//! input never leave the WebSocket.
+//!
+//! Browser viewers cannot open UDP sockets, so the lane ends at the relay and
+//! viewers keep receiving frames over their WebSocket.
+//!
+//! An association nobody says hello on within the probe window, or that goes
+//! quiet later, is closed and the agent told to go back to the WebSocket.
+
+use axum::extract::ws::Message;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use super::datagram::{self, JitterBuffer, LaneMonitor, LaneStatus, LaneTimings, LaneToken, PacketKind, Shard};
+use crate::device_manager::DeviceManager;
+
+/// How often frames held back by jitter buffers and silent associations are checked
+pub const TICK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Largest datagram read; shards are well below it
+const MAX_DATAGRAM: usize = 2048;
+
+/// Association details the agent gets over the WebSocket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LaneOffer {
+    pub port: u16,
+    /// Association token, base64
+    pub token: String,
+}
+
+/// What handling a datagram or a tick produced
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LaneOutput {
+    /// Reassembled frames by agent
+    pub frames: Vec<(Uuid, Vec<u8>)>,
+    /// Sessions whose agent owes a keyframe after lost frames
+    pub keyframe_requests: Vec<Uuid>,
+}
+
+/// An association that failed, with the sessions that were using it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosedLane {
+    pub agent_id: Uuid,
+    pub sessions: Vec<Uuid>,
+}
+
+#[derive(Debug)]
+struct Association {
+    agent_id: Uuid,
+    sessions: HashSet<Uuid>,
+    /// Address the agent said hello from; shards from elsewhere are ignored
+    peer: Option<SocketAddr>,
+    monitor: LaneMonitor,
+    buffer: JitterBuffer,
+}
+
+impl Association {
+    fn drain(&mut self, now: Instant, output: &mut LaneOutput) {
+        output.frames.extend(self.buffer.poll(now).into_iter().map(|frame| (self.agent_id, frame.data)));
+        if self.buffer.take_keyframe_request() {
+            output.keyframe_requests.extend(self.sessions.iter().copied());
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Associations {
+    by_token: HashMap<LaneToken, Association>,
+    by_agent: HashMap<Uuid, LaneToken>,
+}
+
+impl Associations {
+    fn remove_agent(&mut self, agent_id: Uuid) -> Option<Association> {
+        let token = self.by_agent.remove(&agent_id)?;
+        self.by_token.remove(&token)
+    }
+}
+
+/// The relay's UDP socket and the associations bound to it
+pub struct UdpLane {
+    socket: UdpSocket,
+    port: u16,
+    timings: LaneTimings,
+    associations: Mutex<Associations>,
+}
+
+impl UdpLane {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_socket(UdpSocket::bind(addr).await?)
+    }
+
+    /// Serve the lane on an already bound socket, e.g. a dual-stack one
+    pub fn from_socket(socket: UdpSocket) -> io::Result<Self> {
+        let port = socket.local_addr()?.port();
+        Ok(Self { socket, port, timings: LaneTimings::default(), associations: Mutex::new(Associations::default()) })
+    }
+
+    pub fn with_timings(mut self, timings: LaneTimings) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Association for a session's agent, shared with its other sessions
+    pub async fn offer(&self, agent_id: Uuid, session_id: Uuid, now: Instant) -> LaneOffer {
+        let mut associations = self.associations.lock().await;
+        let token = match associations.by_agent.get(&agent_id) {
+            Some(token) => *token,
+            None => {
+                let token = *Uuid::new_v4().as_bytes();
+                let association = Association {
+                    agent_id,
+                    sessions: HashSet::new(),
+                    peer: None,
+                    monitor: LaneMonitor::new(self.timings, now),
+                    buffer: JitterBuffer::default(),
+                };
+                associations.by_token.insert(token, association);
+                associations.by_agent.insert(agent_id, token);
+                token
+            }
+        };
+        if let Some(association) = associations.by_token.get_mut(&token) {
+            association.sessions.insert(session_id);
+        }
+        LaneOffer { port: self.port, token: BASE64.encode(token) }
+    }
+
+    /// Take a session off its agent's association, closing it with the last one
+    pub async fn release(&self, agent_id: Uuid, session_id: Uuid) {
+        let mut associations = self.associations.lock().await;
+        let Some(token) = associations.by_agent.get(&agent_id).copied() else {
+            return;
+        };
+        let empty = associations.by_token.get_mut(&token).is_some_and(|association| {
+            association.sessions.remove(&session_id);
+            association.sessions.is_empty()
+        });
+        if empty {
+            associations.remove_agent(agent_id);
+        }
+    }
+
+    /// Close an agent's association, returning the sessions that used it
+    pub async fn close(&self, agent_id: Uuid) -> Vec<Uuid> {
+        let association = self.associations.lock().await.remove_agent(agent_id);
+        association.map(|association| association.sessions.into_iter().collect()).unwrap_or_default()
+    }
+
+    /// Whether an agent has an association
+    pub async fn is_open(&self, agent_id: Uuid) -> bool {
+        self.associations.lock().await.by_agent.contains_key(&agent_id)
+    }
+
+    /// Receive and handle one datagram, answering it where needed
+    pub async fn recv(&self) -> io::Result<LaneOutput> {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let (len, from) = self.socket.recv_from(&mut buf).await?;
+        let (output, reply) = self.handle_datagram(&buf[..len], from, Instant::now()).await;
+        if let Some(reply) = reply {
+            self.socket.send_to(&reply, from).await?;
+        }
+        Ok(output)
+    }
+
+    async fn handle_datagram(&self, data: &[u8], from: SocketAddr, now: Instant) -> (LaneOutput, Option<Vec<u8>>) {
+        let mut output = LaneOutput::default();
+        let Some((kind, token, body)) = datagram::decode_packet(data) else {
+            return (output, None);
+        };
+        let mut associations = self.associations.lock().await;
+        let Some(association) = associations.by_token.get_mut(&token) else {
+            debug!("UDP lane datagram from {} with an unknown token", from);
+            return (output, None);
+        };
+
+        if kind == PacketKind::Hello {
+            // The agent's NAT may have mapped it to a new port; the token vouches for it
+            if association.peer != Some(from) {
+                info!("UDP lane of agent {} bound to {}", association.agent_id, from);
+            }
+            association.peer = Some(from);
+            association.monitor.heard(now);
+            return (output, Some(datagram::encode_packet(PacketKind::HelloAck, &token, &[])));
+        }
+        if association.peer != Some(from) {
+            return (output, None);
+        }
+        association.monitor.heard(now);
+
+        match kind {
+            PacketKind::Shard => {
+                if let Some(shard) = Shard::decode(body) {
+                    association.buffer.push(shard, now);
+                    association.drain(now, &mut output);
+                }
+                (output, None)
+            }
+            PacketKind::Keepalive => (output, Some(datagram::encode_packet(PacketKind::Keepalive, &token, &[]))),
+            PacketKind::Hello | PacketKind::HelloAck => (output, None),
+        }
+    }
+
+    /// Release frames that waited out the jitter window and close
+    /// associations that were never used or went quiet
+    pub async fn tick(&self, now: Instant) -> (LaneOutput, Vec<ClosedLane>) {
+        let mut output = LaneOutput::default();
+        let mut associations = self.associations.lock().await;
+        let mut failed = Vec::new();
+        for association in associations.by_token.values_mut() {
+            association.drain(now, &mut output);
+            if association.monitor.status(now) == LaneStatus::Failed {
+                failed.push(association.agent_id);
+            }
+        }
+
+        let closed = failed
+            .into_iter()
+            .filter_map(|agent_id| associations.remove_agent(agent_id))
+            .map(|association| ClosedLane { agent_id: association.agent_id, sessions: association.sessions.into_iter().collect() })
+            .collect();
+        (output, closed)
+    }
+}
+
+/// Serve the lane, handing its frames to the device manager
+pub async fn run(lane: Arc<UdpLane>, device_manager: Arc<DeviceManager>) {
+    info!("UDP lane listening on port {}", lane.port());
+
+    // Ticks run apart from receiving, which must not be cut short mid-datagram
+    let ticker = lane.clone();
+    let manager = device_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let (output, closed) = ticker.tick(Instant::now()).await;
+            for lane in closed {
+                warn!("UDP lane of agent {} is silent, back on the WebSocket", lane.agent_id);
+                manager.udp_lane_closed(lane.agent_id, lane.sessions, true).await;
+            }
+            dispatch(&manager, output).await;
+        }
+    });
+
+    loop {
+        match lane.recv().await {
+            Ok(output) => dispatch(&device_manager, output).await,
+            // ICMP errors from agents that went away surface here
+            Err(e) => debug!("UDP lane receive failed: {}", e),
+        }
+    }
+}
+
+async fn dispatch(device_manager: &Arc<DeviceManager>, output: LaneOutput) {
+    for (agent_id, frame) in output.frames {
+        if let Err(e) = super::handle_agent_message(device_manager, &agent_id.to_string(), Message::Binary(frame)).await {
+            debug!("Dropping UDP lane frame from agent {}: {}", agent_id, e);
+        }
+    }
+    for session_id in output.keyframe_requests {
+        if let Err(e) = device_manager.request_keyframe(session_id).await {
+            debug!("Failed to request a keyframe for session {}: {}", session_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::datagram::FecEncoder;
+
+    async fn lane() -> UdpLane {
+        UdpLane::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    async fn agent_socket(lane: &UdpLane) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(("127.0.0.1", lane.port())).await.unwrap();
+        socket
+    }
+
+    fn token(offer: &LaneOffer) -> LaneToken {
+        BASE64.decode(&offer.token).unwrap().try_into().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_frames_survive_injected_loss() {
+        let lane = lane().await;
+        let (agent, session) = (Uuid::new_v4(), Uuid::new_v4());
+        let token = token(&lane.offer(agent, session, Instant::now()).await);
+        let socket = agent_socket(&lane).await;
+
+        // A wrong token gets no answer
+        socket.send(&datagram::encode_packet(PacketKind::Hello, &[0u8; 16], &[])).await.unwrap();
+        assert_eq!(lane.recv().await.unwrap(), LaneOutput::default());
+        socket.send(&datagram::encode_packet(PacketKind::Hello, &token, &[])).await.unwrap();
+        lane.recv().await.unwrap();
+        let mut ack = [0u8; 64];
+        let len = socket.recv(&mut ack).await.unwrap();
+        assert_eq!(datagram::decode_packet(&ack[..len]).map(|(kind, ..)| kind), Some(PacketKind::HelloAck));
+
+        // Drop one datagram of every parity group of 8 + 1 on the way
+        let frames: Vec<Vec<u8>> = (0..3u8).map(|seed| vec![seed; 20_000]).collect();
+        let mut encoder = FecEncoder::default();
+        let mut sent = 0;
+        for (number, frame) in frames.iter().enumerate() {
+            for (index, shard) in encoder.encode(frame, number == 0).into_iter().enumerate() {
+                if index % 9 == 4 {
+                    continue;
+                }
+                socket.send(&datagram::encode_packet(PacketKind::Shard, &token, &shard.encode())).await.unwrap();
+                sent += 1;
+            }
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..sent {
+            let output = lane.recv().await.unwrap();
+            assert!(output.keyframe_requests.is_empty());
+            received.extend(output.frames);
+        }
+        assert_eq!(received, frames.into_iter().map(|frame| (agent, frame)).collect::<Vec<_>>());
+
+        // Shards with the token but from another address are ignored
+        let stranger = agent_socket(&lane).await;
+        let shard = encoder.encode(&[1, 2, 3], true).remove(0);
+        stranger.send(&datagram::encode_packet(PacketKind::Shard, &token, &shard.encode())).await.unwrap();
+        assert_eq!(lane.recv().await.unwrap(), LaneOutput::default());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_or_silent_lane_is_closed() {
+        let timings = LaneTimings { probe_window: Duration::from_millis(300), ..LaneTimings::default() };
+        let lane = lane().await.with_timings(timings);
+        let (blocked, silent, session) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let opened = Instant::now();
+        lane.offer(blocked, session, opened).await;
+        let token = token(&lane.offer(silent, Uuid::new_v4(), opened).await);
+
+        // Every datagram of the blocked agent is lost; the other one gets through once
+        let socket = agent_socket(&lane).await;
+        socket.send(&datagram::encode_packet(PacketKind::Hello, &token, &[])).await.unwrap();
+        lane.recv().await.unwrap();
+
+        let (_, closed) = lane.tick(opened + timings.probe_window).await;
+        assert_eq!(closed, [ClosedLane { agent_id: blocked, sessions: vec![session] }]);
+        assert!(!lane.is_open(blocked).await);
+        assert!(lane.is_open(silent).await);
+
+        let (_, closed) = lane.tick(Instant::now() + timings.silence_timeout).await;
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].agent_id, silent);
+        assert!(!lane.is_open(silent).await);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_share_an_agents_association() {
+        let lane = lane().await;
+        let (agent, first, second) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let offer = lane.offer(agent, first, Instant::now()).await;
+        assert_eq!(lane.offer(agent, second, Instant::now()).await, offer);
+
+        lane.release(agent, first).await;
+        assert!(lane.is_open(agent).await);
+        lane.release(agent, second).await;
+        assert!(!lane.is_open(agent).await);
+        assert!(lane.close(agent).await.is_empty());
+    }
+}
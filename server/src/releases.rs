@@ -0,0 +1,476 @@
+//! Agent releases
+//!
+//! Admins upload a release manifest together with the agent binary it
+//! describes. Agents poll `GET /api/releases/latest` for their platform,
+//! architecture and channel, then fetch the binary from the manifest's
+//! `download_url`. Manifests are signed offline with the release key whose
+//! public half is pinned in every agent's config, so the server only checks
+//! that a manifest matches its binary; the agents decide whether to trust it.
+
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::{DateTime, Utc};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::sync::RwLock;
+use tracing::{debug, info};
+
+use crate::auth::jwt::{error, AuthUser};
+use crate::models::AuditLog;
+use crate::AppState;
+
+/// Prefix of the bytes a release signature covers; must match the agent
+pub const RELEASE_CONTEXT: &str = "ghostlink-release-v1";
+
+/// Largest agent binary accepted for upload
+pub const MAX_RELEASE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Index of published releases, kept next to the binaries
+const INDEX_FILE: &str = "releases.json";
+
+/// Release track an agent follows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseChannel {
+    #[default]
+    Stable,
+    /// Pre-release builds; beta agents also take stable releases that are newer
+    Beta,
+}
+
+impl ReleaseChannel {
+    /// Whether an agent on this channel takes releases published to `channel`
+    fn accepts(self, channel: ReleaseChannel) -> bool {
+        self == ReleaseChannel::Beta || channel == ReleaseChannel::Stable
+    }
+}
+
+impl std::fmt::Display for ReleaseChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReleaseChannel::Stable => write!(f, "stable"),
+            ReleaseChannel::Beta => write!(f, "beta"),
+        }
+    }
+}
+
+/// Semantic version, ordered by semver precedence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// Pre-release identifiers, e.g. `beta.2`; build metadata is dropped
+    pub pre: Vec<String>,
+}
+
+impl std::str::FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_start_matches('v');
+        let s = s.split('+').next().unwrap_or(s);
+        let (core, pre) = match s.split_once('-') {
+            Some((core, pre)) => (core, pre.split('.').map(str::to_string).collect()),
+            None => (s, Vec::new()),
+        };
+
+        let numbers: Vec<u64> = core
+            .split('.')
+            .map(|part| part.parse().map_err(|_| format!("Invalid version: {}", s)))
+            .collect::<Result<_, _>>()?;
+        let [major, minor, patch] = numbers[..] else {
+            return Err(format!("Invalid version: {}", s));
+        };
+        if pre.iter().any(String::is_empty) {
+            return Err(format!("Invalid pre-release in version: {}", s));
+        }
+        Ok(Self { major, minor, patch, pre })
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                // A release outranks its pre-releases
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => compare_pre(&self.pre, &other.pre),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Numeric identifiers compare numerically and rank below alphanumeric ones
+fn compare_pre(a: &[String], b: &[String]) -> Ordering {
+    for (a, b) in a.iter().zip(b) {
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// A published agent build
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseManifest {
+    pub version: String,
+    #[serde(default)]
+    pub channel: ReleaseChannel,
+    /// `std::env::consts::OS` of the agents it is for
+    pub platform: String,
+    /// `std::env::consts::ARCH` of the agents it is for
+    pub arch: String,
+    /// Size of the binary in bytes
+    pub size: u64,
+    /// Hex SHA-256 of the binary
+    pub sha256: String,
+    /// Base64 Ed25519 signature over [`ReleaseManifest::signed_payload`]
+    pub signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// Where agents download the binary; set by the server on upload
+    #[serde(default)]
+    pub download_url: String,
+    #[serde(default = "Utc::now")]
+    pub published_at: DateTime<Utc>,
+}
+
+impl ReleaseManifest {
+    /// Bytes the release key signs
+    pub fn signed_payload(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}",
+            RELEASE_CONTEXT, self.version, self.channel, self.platform, self.arch, self.size, self.sha256
+        )
+    }
+
+    fn parsed_version(&self) -> Version {
+        // Only manifests with valid versions are ever published
+        self.version.parse().unwrap_or(Version { major: 0, minor: 0, patch: 0, pre: Vec::new() })
+    }
+
+    /// Binary file name in the release store
+    fn file_name(&self) -> String {
+        format!("ghostlink-client-{}-{}-{}", self.version, self.platform, self.arch)
+    }
+}
+
+/// Platform and architecture names may end up in file names and URLs
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Stores release manifests and binaries on disk
+pub struct ReleaseManager {
+    storage_path: PathBuf,
+    releases: RwLock<Vec<ReleaseManifest>>,
+}
+
+impl ReleaseManager {
+    pub fn new(storage_path: PathBuf) -> Self {
+        Self {
+            storage_path,
+            releases: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Create the store and load the releases published so far
+    pub async fn initialize(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.storage_path).await
+            .map_err(|e| format!("Failed to create release directory: {}", e))?;
+
+        let index = self.storage_path.join(INDEX_FILE);
+        let releases: Vec<ReleaseManifest> = match fs::read(&index).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("Corrupt release index {}: {}", index.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Failed to read release index: {}", e)),
+        };
+
+        info!("Loaded {} agent releases from {:?}", releases.len(), self.storage_path);
+        *self.releases.write().await = releases;
+        Ok(())
+    }
+
+    /// Store a release, replacing an earlier upload of the same build
+    pub async fn publish(&self, mut manifest: ReleaseManifest, binary: &[u8]) -> Result<ReleaseManifest, String> {
+        manifest.version.parse::<Version>()?;
+        if !is_safe_name(&manifest.platform) || !is_safe_name(&manifest.arch) {
+            return Err("Platform and arch may only contain letters, digits, '-' and '_'".to_string());
+        }
+        if manifest.size != binary.len() as u64 {
+            return Err(format!("Manifest size {} does not match the {} byte binary", manifest.size, binary.len()));
+        }
+        if !manifest.sha256.eq_ignore_ascii_case(&hex_digest(binary)) {
+            return Err("Manifest sha256 does not match the binary".to_string());
+        }
+        match BASE64.decode(&manifest.signature) {
+            Ok(signature) if signature.len() == 64 => {}
+            _ => return Err("Signature must be a base64 Ed25519 signature".to_string()),
+        }
+
+        manifest.sha256 = manifest.sha256.to_ascii_lowercase();
+        manifest.download_url = format!(
+            "/api/releases/{}/{}/{}/binary",
+            manifest.platform, manifest.arch, manifest.version
+        );
+        manifest.published_at = Utc::now();
+
+        let mut releases = self.releases.write().await;
+        fs::write(self.storage_path.join(manifest.file_name()), binary).await
+            .map_err(|e| format!("Failed to store release binary: {}", e))?;
+        releases.retain(|release| {
+            (&release.version, &release.platform, &release.arch) != (&manifest.version, &manifest.platform, &manifest.arch)
+        });
+        releases.push(manifest.clone());
+
+        let index = serde_json::to_vec_pretty(&*releases).map_err(|e| e.to_string())?;
+        fs::write(self.storage_path.join(INDEX_FILE), index).await
+            .map_err(|e| format!("Failed to write release index: {}", e))?;
+
+        info!("Published agent {} for {}/{} on {}", manifest.version, manifest.platform, manifest.arch, manifest.channel);
+        Ok(manifest)
+    }
+
+    /// Newest release an agent on `channel` should run
+    pub async fn latest(&self, platform: &str, arch: &str, channel: ReleaseChannel) -> Option<ReleaseManifest> {
+        self.releases.read().await
+            .iter()
+            .filter(|release| release.platform == platform && release.arch == arch && channel.accepts(release.channel))
+            .max_by_key(|release| release.parsed_version())
+            .cloned()
+    }
+
+    /// Binary of a published release
+    pub async fn read_binary(&self, platform: &str, arch: &str, version: &str) -> Result<Vec<u8>, String> {
+        let release = self.releases.read().await
+            .iter()
+            .find(|release| release.platform == platform && release.arch == arch && release.version == version)
+            .cloned()
+            .ok_or_else(|| format!("No {} release for {}/{}", version, platform, arch))?;
+
+        fs::read(self.storage_path.join(release.file_name())).await
+            .map_err(|e| format!("Binary for {} is missing: {}", version, e))
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    digest(&SHA256, data).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Update outcome an agent reports with its heartbeats
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpdateReport {
+    /// "pending" while a new version waits to prove itself, "failed" once
+    /// an update was abandoned or rolled back
+    pub status: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LatestReleaseQuery {
+    pub platform: String,
+    pub arch: String,
+    #[serde(default)]
+    pub channel: ReleaseChannel,
+}
+
+/// Newest release for an agent's platform, architecture and channel
+pub async fn api_get_latest_release(
+    State(app_state): State<AppState>,
+    Query(query): Query<LatestReleaseQuery>,
+) -> Response {
+    let releases = &app_state.device_manager.release_manager;
+    match releases.latest(&query.platform, &query.arch, query.channel).await {
+        Some(manifest) => Json(manifest).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(serde_json::json!({
+            "error": format!("No {} release for {}/{}", query.channel, query.platform, query.arch)
+        }))).into_response(),
+    }
+}
+
+/// Download a release binary
+pub async fn api_download_release(
+    State(app_state): State<AppState>,
+    Path((platform, arch, version)): Path<(String, String, String)>,
+) -> Response {
+    match app_state.device_manager.release_manager.read_binary(&platform, &arch, &version).await {
+        Ok(data) => {
+            debug!("Serving agent {} for {}/{} ({} bytes)", version, platform, arch, data.len());
+            ([(header::CONTENT_TYPE, "application/octet-stream")], data).into_response()
+        }
+        Err(e) => error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Upload a release (admin only): a multipart form with a `manifest` JSON
+/// part and a `binary` part
+pub async fn api_upload_release(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    mut multipart: Multipart,
+) -> Response {
+    let bad_request = |message: String| {
+        error(StatusCode::BAD_REQUEST, message)
+    };
+
+    let mut manifest: Option<ReleaseManifest> = None;
+    let mut binary: Option<Vec<u8>> = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return bad_request(format!("Malformed upload: {}", e)),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let data = match field.bytes().await {
+            Ok(data) => data,
+            Err(e) => return bad_request(format!("Failed to read {}: {}", name, e)),
+        };
+        match name.as_str() {
+            "manifest" => match serde_json::from_slice(&data) {
+                Ok(parsed) => manifest = Some(parsed),
+                Err(e) => return bad_request(format!("Invalid manifest: {}", e)),
+            },
+            "binary" => binary = Some(data.to_vec()),
+            other => debug!("Ignoring upload field {}", other),
+        }
+    }
+    let (Some(manifest), Some(binary)) = (manifest, binary) else {
+        return bad_request("Upload needs a manifest and a binary".to_string());
+    };
+
+    let device_manager = &app_state.device_manager;
+    match device_manager.release_manager.publish(manifest, &binary).await {
+        Ok(manifest) => {
+            device_manager.record_audit(
+                AuditLog::new("release", "release_published")
+                    .actor(user.email)
+                    .details(serde_json::json!({
+                        "version": manifest.version,
+                        "channel": manifest.channel,
+                        "platform": manifest.platform,
+                        "arch": manifest.arch,
+                        "sha256": manifest.sha256,
+                    })),
+            ).await;
+            (StatusCode::CREATED, Json(manifest)).into_response()
+        }
+        Err(e) => bad_request(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    fn store() -> ReleaseManager {
+        ReleaseManager::new(std::env::temp_dir().join(format!("ghostlink-releases-{}", Uuid::new_v4())))
+    }
+
+    fn manifest(version: &str, channel: ReleaseChannel, binary: &[u8]) -> ReleaseManifest {
+        ReleaseManifest {
+            version: version.to_string(),
+            channel,
+            platform: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            size: binary.len() as u64,
+            sha256: hex_digest(binary),
+            signature: BASE64.encode([7u8; 64]),
+            notes: None,
+            download_url: String::new(),
+            published_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_version_precedence() {
+        let ordered = ["0.9.0", "1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta.2", "1.0.0-beta.11", "1.0.0-rc.1", "1.0.0", "1.0.1", "1.10.0"];
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(version("v1.2.3+build.5"), version("1.2.3"));
+        for invalid in ["1.2", "1.2.3.4", "1.x.3", "1.2.3-", "1.2.3-beta..1", ""] {
+            assert!(invalid.parse::<Version>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latest_follows_channel() {
+        let releases = store();
+        releases.initialize().await.unwrap();
+        releases.publish(manifest("1.1.0", ReleaseChannel::Stable, b"stable"), b"stable").await.unwrap();
+        releases.publish(manifest("1.2.0-beta.1", ReleaseChannel::Beta, b"beta"), b"beta").await.unwrap();
+
+        let stable = releases.latest("linux", "x86_64", ReleaseChannel::Stable).await.unwrap();
+        assert_eq!(stable.version, "1.1.0");
+        assert_eq!(stable.download_url, "/api/releases/linux/x86_64/1.1.0/binary");
+        assert_eq!(releases.latest("linux", "x86_64", ReleaseChannel::Beta).await.unwrap().version, "1.2.0-beta.1");
+        assert!(releases.latest("windows", "x86_64", ReleaseChannel::Beta).await.is_none());
+
+        // Promoting the final build puts beta agents back on stable
+        releases.publish(manifest("1.2.0", ReleaseChannel::Stable, b"final"), b"final").await.unwrap();
+        assert_eq!(releases.latest("linux", "x86_64", ReleaseChannel::Beta).await.unwrap().version, "1.2.0");
+        assert_eq!(releases.read_binary("linux", "x86_64", "1.2.0").await.unwrap(), b"final");
+
+        // The index survives a restart
+        let reloaded = ReleaseManager::new(releases.storage_path.clone());
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.latest("linux", "x86_64", ReleaseChannel::Stable).await.unwrap().version, "1.2.0");
+        let _ = std::fs::remove_dir_all(&releases.storage_path);
+    }
+
+    #[tokio::test]
+    async fn test_publish_rejects_mismatched_uploads() {
+        let releases = store();
+        releases.initialize().await.unwrap();
+
+        let mut wrong_hash = manifest("1.0.0", ReleaseChannel::Stable, b"binary");
+        wrong_hash.sha256 = hex_digest(b"other");
+        assert!(releases.publish(wrong_hash, b"binary").await.unwrap_err().contains("sha256"));
+
+        let wrong_size = manifest("1.0.0", ReleaseChannel::Stable, b"binary");
+        assert!(releases.publish(wrong_size, b"binary!").await.unwrap_err().contains("size"));
+
+        let mut bad_platform = manifest("1.0.0", ReleaseChannel::Stable, b"binary");
+        bad_platform.platform = "../linux".to_string();
+        assert!(releases.publish(bad_platform, b"binary").await.is_err());
+
+        let mut unsigned = manifest("1.0.0", ReleaseChannel::Stable, b"binary");
+        unsigned.signature = "not a signature".to_string();
+        assert!(releases.publish(unsigned, b"binary").await.is_err());
+
+        assert!(releases.latest("linux", "x86_64", ReleaseChannel::Beta).await.is_none());
+        let _ = std::fs::remove_dir_all(&releases.storage_path);
+    }
+}
@@ -0,0 +1,125 @@
+//! Session timeline events
+//!
+//! The technician's session window reports what happens in a session (notes,
+//! chat, commands, tool launches, tab switches, backstage mode and file
+//! transfers) to the server, which keeps them for the web Timeline tab and
+//! pushes them to the session's viewers as they arrive.
+//!
+//! The window assigns every event its ID, so an event it sends again after a
+//! failed upload is recognised and stored once.
+//!
+//! This file is kept identical in the client and the server.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// What a timeline event records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEventKind {
+    Note,
+    Message,
+    Command,
+    ToolLaunch,
+    TabSwitch,
+    BackstageToggle,
+    FileTransfer,
+    /// A file renamed or deleted through the file browser
+    FileOperation,
+    /// Anything else the window shows on its timeline, named in the details'
+    /// `event_type`; also what kinds from newer builds read as
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEvent {
+    /// Assigned by the session window
+    pub id: Uuid,
+    pub session_id: Uuid,
+    /// Position in the window's stream, breaking ties between equal timestamps
+    #[serde(default)]
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Who caused the event: the technician, or the end user for their chat
+    pub actor: String,
+    pub kind: SessionEventKind,
+    #[serde(default)]
+    pub details: serde_json::Value,
+}
+
+impl SessionEvent {
+    pub fn new(session_id: Uuid, kind: SessionEventKind, actor: impl Into<String>, details: serde_json::Value) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            session_id,
+            sequence: 0,
+            timestamp: Utc::now(),
+            actor: actor.into(),
+            kind,
+            details,
+        }
+    }
+
+    /// Timeline order: time first, then the order the window produced them
+    pub fn order_key(&self) -> (DateTime<Utc>, u64, Uuid) {
+        (self.timestamp, self.sequence, self.id)
+    }
+}
+
+/// Add `incoming` to `timeline`, which is in timeline order, skipping events
+/// already in it. Returns the events that were new, in timeline order.
+pub fn merge_events(timeline: &mut Vec<SessionEvent>, incoming: Vec<SessionEvent>) -> Vec<SessionEvent> {
+    let mut added: Vec<SessionEvent> = Vec::new();
+    for event in incoming {
+        if timeline.iter().chain(&added).any(|known| known.id == event.id) {
+            continue;
+        }
+        added.push(event);
+    }
+    added.sort_by_key(SessionEvent::order_key);
+
+    for event in &added {
+        let position = timeline.partition_point(|known| known.order_key() <= event.order_key());
+        timeline.insert(position, event.clone());
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(sequence: u64, seconds: i64) -> SessionEvent {
+        let mut event = SessionEvent::new(Uuid::nil(), SessionEventKind::Note, "tech@example.com", serde_json::json!({}));
+        event.sequence = sequence;
+        event.timestamp = DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap();
+        event
+    }
+
+    #[test]
+    fn test_merge_orders_and_deduplicates() {
+        let (first, second, third) = (event(1, 0), event(2, 0), event(3, 5));
+        let mut timeline = vec![first.clone(), third.clone()];
+
+        let added = merge_events(&mut timeline, vec![third.clone(), second.clone(), second.clone()]);
+        assert_eq!(added, vec![second.clone()]);
+        assert_eq!(timeline, vec![first, second, third]);
+    }
+
+    #[test]
+    fn test_kind_wire_format() {
+        let event = event(1, 0);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["kind"], "note");
+
+        let mut future = json.clone();
+        future["kind"] = serde_json::json!("screen_annotation");
+        future.as_object_mut().unwrap().remove("sequence");
+        let parsed: SessionEvent = serde_json::from_value(future).unwrap();
+        assert_eq!(parsed.kind, SessionEventKind::Other);
+        assert_eq!(parsed.sequence, 0);
+        assert_eq!(serde_json::from_value::<SessionEventKind>("tool_launch".into()).unwrap(), SessionEventKind::ToolLaunch);
+    }
+}
@@ -0,0 +1,777 @@
+//! Notes technicians keep on sessions, and the templates they start from
+//!
+//! A session note is markdown with its author; editing one keeps what it said
+//! before in its history, with who changed it. Private notes are seen by
+//! their author and admins only. Admins keep templates for their
+//! organization under `/api/note-templates`, which the session window offers
+//! when a note is written.
+//!
+//! With the `closeout_note_required` policy a technician writes a close-out
+//! note before ending a session: `DELETE /api/sessions/:id` answers 412 until
+//! the session has one. Sessions the server or the agent ends are not held
+//! up. A session's `ticket_ref` links it to a ticket in the organization's
+//! PSA, and goes along in its audit entries and webhooks.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as DbJson;
+use sqlx::FromRow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::api::authorized_session;
+use crate::audit::RequestContext;
+use crate::auth::{access_grants, authz, jwt::{error, AuthUser}};
+use crate::database::DatabaseService;
+use crate::device_records::MAX_NOTE_LENGTH;
+use crate::models::{AuditLog, Session};
+use crate::organizations::{self, Tenant};
+use crate::webhooks::WebhookEvent;
+use crate::AppState;
+
+/// Most templates one organization may keep
+pub const MAX_TEMPLATES: usize = 100;
+
+/// Longest template name, in characters
+const MAX_TEMPLATE_NAME_LENGTH: usize = 100;
+
+/// Earlier versions kept of each note
+const MAX_NOTE_REVISIONS: usize = 50;
+
+/// Longest ticket reference, in characters
+pub const MAX_TICKET_REF_LENGTH: usize = 128;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NoteError {
+    /// No template or note with this ID the caller can see
+    NotFound(&'static str),
+    /// Another template of the organization already has this name
+    DuplicateName,
+    /// Only the author and admins change a note
+    NotAuthor,
+    Invalid(String),
+}
+
+impl IntoResponse for NoteError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            NoteError::NotFound(what) => (StatusCode::NOT_FOUND, format!("{} not found", what)),
+            NoteError::DuplicateName => (StatusCode::CONFLICT, "A template with this name already exists".to_string()),
+            NoteError::NotAuthor => (StatusCode::FORBIDDEN, "Only the author of a note or an admin may edit it".to_string()),
+            NoteError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        error(status, message)
+    }
+}
+
+/// A template an organization offers for session notes
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct NoteTemplate {
+    pub id: Uuid,
+    pub organization_id: Option<Uuid>,
+    pub name: String,
+    /// Markdown a note written from the template starts with
+    pub body: String,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A template as an admin defines it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateDefinition {
+    pub name: String,
+    pub body: String,
+}
+
+/// Changes to a template
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateUpdate {
+    pub name: Option<String>,
+    pub body: Option<String>,
+}
+
+/// What a note said before an edit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteRevision {
+    pub body: String,
+    /// Who replaced it
+    pub edited_by: Option<String>,
+    pub edited_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct SessionNote {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub organization_id: Option<Uuid>,
+    /// Markdown
+    pub body: String,
+    /// Template the note was written from
+    pub template_id: Option<Uuid>,
+    /// Seen by the author and admins only
+    pub private: bool,
+    /// Written to close the session out
+    pub closeout: bool,
+    pub author_id: Option<Uuid>,
+    pub author_email: Option<String>,
+    /// Earlier versions, oldest first
+    pub history: DbJson<Vec<NoteRevision>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NoteRequest {
+    /// Assigned by the session window, so a note it sends again after a
+    /// failed upload is stored once
+    pub id: Option<Uuid>,
+    pub body: String,
+    pub template_id: Option<Uuid>,
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub closeout: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteEdit {
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TicketRequest {
+    /// Null or empty unlinks the session
+    pub ticket_ref: Option<String>,
+}
+
+fn normalize_name(name: &str) -> Result<String, NoteError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_TEMPLATE_NAME_LENGTH || name.chars().any(char::is_control) {
+        return Err(NoteError::Invalid(format!(
+            "Template names are 1 to {} characters without control characters", MAX_TEMPLATE_NAME_LENGTH
+        )));
+    }
+    Ok(name.to_string())
+}
+
+fn check_body(body: &str) -> Result<(), NoteError> {
+    if body.len() > MAX_NOTE_LENGTH {
+        return Err(NoteError::Invalid(format!("Notes are at most {} bytes", MAX_NOTE_LENGTH)));
+    }
+    Ok(())
+}
+
+/// Ticket reference trimmed, `None` when empty, or why it can't be used
+pub fn normalize_ticket_ref(ticket_ref: Option<&str>) -> Result<Option<String>, String> {
+    let Some(ticket_ref) = ticket_ref.map(str::trim).filter(|ticket_ref| !ticket_ref.is_empty()) else {
+        return Ok(None);
+    };
+    if ticket_ref.chars().count() > MAX_TICKET_REF_LENGTH || ticket_ref.chars().any(char::is_control) {
+        return Err(format!(
+            "Ticket references are at most {} characters without control characters", MAX_TICKET_REF_LENGTH
+        ));
+    }
+    Ok(Some(ticket_ref.to_string()))
+}
+
+/// Whether `user` sees `note`
+fn visible_to(note: &SessionNote, user: &AuthUser) -> bool {
+    !note.private || note.author_id == Some(user.user_id) || is_admin(user)
+}
+
+fn is_admin(user: &AuthUser) -> bool {
+    authz::user_role(user).is_ok_and(|role| role.is_admin())
+}
+
+/// Note templates of every organization and the notes of recent sessions
+pub struct SessionNotes {
+    db: Option<Arc<DatabaseService>>,
+    templates: RwLock<HashMap<Uuid, NoteTemplate>>,
+    /// Notes per session, oldest first; sessions are loaded from the
+    /// database when first asked for
+    notes: RwLock<HashMap<Uuid, Vec<SessionNote>>>,
+}
+
+impl Default for SessionNotes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionNotes {
+    pub fn new() -> Self {
+        Self {
+            db: None,
+            templates: RwLock::new(HashMap::new()),
+            notes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Keep templates and notes in `db`
+    pub fn with_database(mut self, db: Arc<DatabaseService>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Load the templates a previous run stored
+    pub async fn initialize(&self) -> Result<(), String> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let templates = db.list_note_templates().await
+            .map_err(|e| format!("Failed to load note templates: {}", e))?;
+        info!("Loaded {} note templates", templates.len());
+        *self.templates.write().await = templates.into_iter().map(|template| (template.id, template)).collect();
+        Ok(())
+    }
+
+    /// Templates of `tenant`, by name
+    pub async fn list_templates(&self, tenant: Tenant) -> Vec<NoteTemplate> {
+        let mut templates: Vec<_> = self.templates.read().await.values()
+            .filter(|template| tenant.includes(template.organization_id))
+            .cloned()
+            .collect();
+        templates.sort_by_key(|template| template.name.to_lowercase());
+        templates
+    }
+
+    pub async fn create_template(
+        &self,
+        organization_id: Option<Uuid>,
+        definition: TemplateDefinition,
+        created_by: Uuid,
+    ) -> Result<NoteTemplate, NoteError> {
+        let name = normalize_name(&definition.name)?;
+        check_body(&definition.body)?;
+        let mut templates = self.templates.write().await;
+        let organization_templates = || templates.values().filter(|template| template.organization_id == organization_id);
+        if organization_templates().any(|template| template.name.eq_ignore_ascii_case(&name)) {
+            return Err(NoteError::DuplicateName);
+        }
+        if organization_templates().count() >= MAX_TEMPLATES {
+            return Err(NoteError::Invalid(format!("An organization can keep at most {} templates", MAX_TEMPLATES)));
+        }
+
+        let now = Utc::now();
+        let template = NoteTemplate {
+            id: Uuid::new_v4(),
+            organization_id,
+            name,
+            body: definition.body,
+            created_by: Some(created_by),
+            created_at: now,
+            updated_at: now,
+        };
+        templates.insert(template.id, template.clone());
+        drop(templates);
+        self.persist_template(&template).await;
+        Ok(template)
+    }
+
+    pub async fn update_template(&self, tenant: Tenant, template_id: Uuid, update: TemplateUpdate) -> Result<NoteTemplate, NoteError> {
+        let name = update.name.as_deref().map(normalize_name).transpose()?;
+        if let Some(body) = &update.body {
+            check_body(body)?;
+        }
+        let mut templates = self.templates.write().await;
+        let organization_id = templates.get(&template_id)
+            .filter(|template| tenant.includes(template.organization_id))
+            .ok_or(NoteError::NotFound("Template"))?
+            .organization_id;
+        if let Some(name) = &name {
+            let taken = templates.values().any(|other| {
+                other.id != template_id && other.organization_id == organization_id && other.name.eq_ignore_ascii_case(name)
+            });
+            if taken {
+                return Err(NoteError::DuplicateName);
+            }
+        }
+
+        let Some(template) = templates.get_mut(&template_id) else {
+            return Err(NoteError::NotFound("Template"));
+        };
+        if let Some(name) = name {
+            template.name = name;
+        }
+        if let Some(body) = update.body {
+            template.body = body;
+        }
+        template.updated_at = Utc::now();
+        let template = template.clone();
+        drop(templates);
+        self.persist_template(&template).await;
+        Ok(template)
+    }
+
+    /// Delete a template; notes written from it keep their text
+    pub async fn delete_template(&self, tenant: Tenant, template_id: Uuid) -> Result<NoteTemplate, NoteError> {
+        let mut templates = self.templates.write().await;
+        if !templates.get(&template_id).is_some_and(|template| tenant.includes(template.organization_id)) {
+            return Err(NoteError::NotFound("Template"));
+        }
+        let Some(template) = templates.remove(&template_id) else {
+            return Err(NoteError::NotFound("Template"));
+        };
+        drop(templates);
+        if let Some(db) = &self.db {
+            if let Err(e) = db.delete_note_template(template_id).await {
+                warn!("Failed to delete note template {}: {}", template_id, e);
+            }
+        }
+        Ok(template)
+    }
+
+    async fn persist_template(&self, template: &NoteTemplate) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.upsert_note_template(template).await {
+                warn!("Failed to store note template {}: {}", template.id, e);
+            }
+        }
+    }
+
+    /// Notes of a session `user` may see, oldest first
+    pub async fn notes(&self, session_id: Uuid, user: &AuthUser) -> Vec<SessionNote> {
+        self.load(session_id).await;
+        self.notes.read().await.get(&session_id)
+            .map(|notes| notes.iter().filter(|note| visible_to(note, user)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether a session has a close-out note
+    pub async fn has_closeout(&self, session_id: Uuid) -> bool {
+        self.load(session_id).await;
+        self.notes.read().await.get(&session_id)
+            .is_some_and(|notes| notes.iter().any(|note| note.closeout))
+    }
+
+    /// Add a note to `session`. A request with the ID of a note `author`
+    /// already added returns that note and `false` instead.
+    pub async fn add_note(&self, session: &Session, request: NoteRequest, author: &AuthUser) -> Result<(SessionNote, bool), NoteError> {
+        check_body(&request.body)?;
+        if request.body.trim().is_empty() {
+            return Err(NoteError::Invalid("A note needs some text".to_string()));
+        }
+        if request.private && request.closeout {
+            return Err(NoteError::Invalid("Close-out notes can't be private".to_string()));
+        }
+        if let Some(template_id) = request.template_id {
+            let tenant = Tenant::Organization(session.organization_id);
+            if !self.templates.read().await.get(&template_id).is_some_and(|template| tenant.includes(template.organization_id)) {
+                return Err(NoteError::NotFound("Template"));
+            }
+        }
+
+        self.load(session.id).await;
+        let mut all = self.notes.write().await;
+        let notes = all.entry(session.id).or_default();
+        if let Some(id) = request.id {
+            if let Some(existing) = notes.iter().find(|note| note.id == id) {
+                if existing.author_id != Some(author.user_id) {
+                    return Err(NoteError::Invalid(format!("Note {} already exists", id)));
+                }
+                return Ok((existing.clone(), false));
+            }
+        }
+
+        let now = Utc::now();
+        let note = SessionNote {
+            id: request.id.unwrap_or_else(Uuid::new_v4),
+            session_id: session.id,
+            organization_id: session.organization_id,
+            body: request.body,
+            template_id: request.template_id,
+            private: request.private,
+            closeout: request.closeout,
+            author_id: Some(author.user_id),
+            author_email: Some(author.email.clone()),
+            history: DbJson(Vec::new()),
+            created_at: now,
+            updated_at: now,
+        };
+        notes.push(note.clone());
+        drop(all);
+        self.persist_note(&note).await;
+        Ok((note, true))
+    }
+
+    /// Replace a note's text, keeping the previous text in its history
+    pub async fn edit_note(&self, session_id: Uuid, note_id: Uuid, body: String, editor: &AuthUser) -> Result<SessionNote, NoteError> {
+        check_body(&body)?;
+        if body.trim().is_empty() {
+            return Err(NoteError::Invalid("A note needs some text".to_string()));
+        }
+        self.load(session_id).await;
+        let mut all = self.notes.write().await;
+        let note = all.get_mut(&session_id)
+            .and_then(|notes| notes.iter_mut().find(|note| note.id == note_id))
+            .filter(|note| visible_to(note, editor))
+            .ok_or(NoteError::NotFound("Note"))?;
+        if note.author_id != Some(editor.user_id) && !is_admin(editor) {
+            return Err(NoteError::NotAuthor);
+        }
+        if note.body == body {
+            return Ok(note.clone());
+        }
+
+        let now = Utc::now();
+        let previous = std::mem::replace(&mut note.body, body);
+        note.history.0.push(NoteRevision { body: previous, edited_by: Some(editor.email.clone()), edited_at: now });
+        if note.history.0.len() > MAX_NOTE_REVISIONS {
+            let excess = note.history.0.len() - MAX_NOTE_REVISIONS;
+            note.history.0.drain(..excess);
+        }
+        note.updated_at = now;
+        let note = note.clone();
+        drop(all);
+        self.persist_note(&note).await;
+        Ok(note)
+    }
+
+    /// Drop the notes of a session from memory; the database keeps them
+    pub async fn forget(&self, session_id: Uuid) {
+        self.notes.write().await.remove(&session_id);
+    }
+
+    /// Read a session's notes from the database unless they are in memory
+    async fn load(&self, session_id: Uuid) {
+        let Some(db) = &self.db else {
+            return;
+        };
+        if self.notes.read().await.contains_key(&session_id) {
+            return;
+        }
+        let stored = match db.list_session_notes(session_id).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("Failed to load notes of session {}: {}", session_id, e);
+                return;
+            }
+        };
+        self.notes.write().await.entry(session_id).or_insert(stored);
+    }
+
+    async fn persist_note(&self, note: &SessionNote) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.upsert_session_note(note).await {
+                warn!("Failed to store note {} of session {}: {}", note.id, note.session_id, e);
+            }
+        }
+    }
+}
+
+/// The session, if `user` may see it; guests only reach the sessions of
+/// their access grant
+async fn permitted_session(app_state: &AppState, user: &AuthUser, session_id: &str, needs_control: bool) -> Result<Session, Response> {
+    let session = authorized_session(app_state, user, session_id, needs_control).await?;
+    match access_grants::guest_grant(app_state, user).await {
+        Ok(Some(grant)) if !grant.sessions.contains(&session.id) => {
+            Err(organizations::session_not_found(session.id).into_response())
+        }
+        Ok(_) => Ok(session),
+        Err(e) => Err(e.into_response()),
+    }
+}
+
+/// Note templates of the caller's organization
+pub async fn api_list_note_templates(State(app_state): State<AppState>, tenant: Tenant) -> Response {
+    let templates = app_state.device_manager.session_notes.list_templates(tenant).await;
+    Json(serde_json::json!({ "templates": templates })).into_response()
+}
+
+pub async fn api_create_note_template(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Json(definition): Json<TemplateDefinition>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    match device_manager.session_notes.create_template(tenant.organization_id(), definition, user.user_id).await {
+        Ok(template) => {
+            device_manager.record_audit(
+                AuditLog::new("session", "note_template_created")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "template_id": template.id, "name": template.name })),
+            ).await;
+            (StatusCode::CREATED, Json(template)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn api_update_note_template(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(template_id): Path<String>,
+    Json(update): Json<TemplateUpdate>,
+) -> Response {
+    let template_id = match organizations::parse_id(&template_id, "template") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.session_notes.update_template(tenant, template_id, update).await {
+        Ok(template) => {
+            device_manager.record_audit(
+                AuditLog::new("session", "note_template_updated")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "template_id": template.id, "name": template.name })),
+            ).await;
+            Json(template).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn api_delete_note_template(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(template_id): Path<String>,
+) -> Response {
+    let template_id = match organizations::parse_id(&template_id, "template") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.session_notes.delete_template(tenant, template_id).await {
+        Ok(template) => {
+            device_manager.record_audit(
+                AuditLog::new("session", "note_template_deleted")
+                    .actor(user.user_id.to_string())
+                    .details(serde_json::json!({ "template_id": template.id, "name": template.name })),
+            ).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Notes of a session the caller may see
+pub async fn api_list_session_notes(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session = match permitted_session(&app_state, &user, &session_id, false).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    let notes = app_state.device_manager.session_notes.notes(session.id, &user).await;
+    Json(serde_json::json!({ "notes": notes, "ticket_ref": session.ticket_ref })).into_response()
+}
+
+/// Add a note to a session; shared notes go out as `session.note.added`
+pub async fn api_add_session_note(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<String>,
+    Json(request): Json<NoteRequest>,
+) -> Response {
+    let session = match permitted_session(&app_state, &user, &session_id, false).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    let device_manager = &app_state.device_manager;
+    let (note, added) = match device_manager.session_notes.add_note(&session, request, &user).await {
+        Ok(result) => result,
+        Err(e) => return e.into_response(),
+    };
+    if !added {
+        return Json(note).into_response();
+    }
+
+    device_manager.record_audit(
+        AuditLog::new("session", "note_added")
+            .actor(user.user_id.to_string())
+            .agent(session.agent_id)
+            .session(session.id)
+            .request(&context)
+            .details(serde_json::json!({
+                "note_id": note.id,
+                "private": note.private,
+                "closeout": note.closeout,
+                "template_id": note.template_id,
+                "ticket_ref": session.ticket_ref,
+            })),
+    ).await;
+    if !note.private {
+        device_manager.webhooks.notify(WebhookEvent::SessionNoteAdded, session.organization_id, serde_json::json!({
+            "session_id": session.id,
+            "ticket_ref": session.ticket_ref,
+            "note": note,
+        })).await;
+    }
+    (StatusCode::CREATED, Json(note)).into_response()
+}
+
+/// Replace the text of a session note
+pub async fn api_edit_session_note(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path((session_id, note_id)): Path<(String, String)>,
+    Json(edit): Json<NoteEdit>,
+) -> Response {
+    let session = match permitted_session(&app_state, &user, &session_id, false).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    let note_id = match organizations::parse_id(&note_id, "note") {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    match device_manager.session_notes.edit_note(session.id, note_id, edit.body, &user).await {
+        Ok(note) => {
+            device_manager.record_audit(
+                AuditLog::new("session", "note_edited")
+                    .actor(user.user_id.to_string())
+                    .agent(session.agent_id)
+                    .session(session.id)
+                    .request(&context)
+                    .details(serde_json::json!({ "note_id": note.id, "revisions": note.history.0.len() })),
+            ).await;
+            Json(note).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Link a session to a PSA ticket, or unlink it
+pub async fn api_set_ticket_ref(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(session_id): Path<String>,
+    Json(request): Json<TicketRequest>,
+) -> Response {
+    let session = match permitted_session(&app_state, &user, &session_id, true).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    let ticket_ref = match normalize_ticket_ref(request.ticket_ref.as_deref()) {
+        Ok(ticket_ref) => ticket_ref,
+        Err(message) => return NoteError::Invalid(message).into_response(),
+    };
+    let device_manager = &app_state.device_manager;
+    let Some(updated) = device_manager.set_ticket_ref(session.id, ticket_ref).await else {
+        return organizations::session_not_found(session.id).into_response();
+    };
+    if updated.ticket_ref != session.ticket_ref {
+        device_manager.record_audit(
+            AuditLog::new("session", "ticket_linked")
+                .actor(user.user_id.to_string())
+                .agent(session.agent_id)
+                .session(session.id)
+                .request(&context)
+                .details(serde_json::json!({ "previous": session.ticket_ref, "ticket_ref": updated.ticket_ref })),
+        ).await;
+    }
+    Json(updated).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(role: &str) -> AuthUser {
+        AuthUser {
+            user_id: Uuid::new_v4(),
+            email: format!("{}@example.com", role),
+            role: role.to_string(),
+            org_id: None,
+            orgs: Vec::new(),
+        }
+    }
+
+    fn note(body: &str) -> NoteRequest {
+        NoteRequest { id: Some(Uuid::new_v4()), body: body.to_string(), template_id: None, private: false, closeout: false }
+    }
+
+    #[tokio::test]
+    async fn test_templates_stay_in_their_organization() {
+        let notes = SessionNotes::new();
+        let organization = Some(Uuid::new_v4());
+        let definition = |name: &str| TemplateDefinition { name: name.to_string(), body: "## Resolution\n".to_string() };
+        let template = notes.create_template(organization, definition(" Printer fix "), Uuid::new_v4()).await.unwrap();
+        assert_eq!(template.name, "Printer fix");
+        assert_eq!(
+            notes.create_template(organization, definition("printer FIX"), Uuid::new_v4()).await.unwrap_err(),
+            NoteError::DuplicateName
+        );
+        notes.create_template(Some(Uuid::new_v4()), definition("Printer fix"), Uuid::new_v4()).await.unwrap();
+        assert_eq!(notes.list_templates(Tenant::Organization(organization)).await.len(), 1);
+
+        let stranger = Tenant::Organization(Some(Uuid::new_v4()));
+        let rename = TemplateUpdate { name: Some("Printers".to_string()), body: None };
+        assert_eq!(notes.update_template(stranger, template.id, rename.clone()).await.unwrap_err(), NoteError::NotFound("Template"));
+        let renamed = notes.update_template(Tenant::Organization(organization), template.id, rename).await.unwrap();
+        assert_eq!((renamed.name.as_str(), renamed.body.as_str()), ("Printers", "## Resolution\n"));
+
+        // Notes may only start from a template of the session's organization
+        let session = crate::models::Session { organization_id: Some(Uuid::new_v4()), ..crate::database::fixtures::session(Uuid::new_v4(), Uuid::new_v4()) };
+        let request = NoteRequest { template_id: Some(template.id), ..note("Replaced toner") };
+        assert_eq!(notes.add_note(&session, request, &user("technician")).await.unwrap_err(), NoteError::NotFound("Template"));
+
+        notes.delete_template(Tenant::Organization(organization), template.id).await.unwrap();
+        assert!(notes.list_templates(Tenant::Organization(organization)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_notes_keep_history_and_privacy() {
+        let notes = SessionNotes::new();
+        let session = crate::database::fixtures::session(Uuid::new_v4(), Uuid::new_v4());
+        let author = user("technician");
+        let colleague = user("technician");
+
+        let request = note("Cleared the print spooler");
+        let (added, new) = notes.add_note(&session, request.clone(), &author).await.unwrap();
+        assert!(new);
+        // The window retrying its upload doesn't add it twice
+        assert!(!notes.add_note(&session, request, &author).await.unwrap().1);
+        assert!(!notes.has_closeout(session.id).await);
+
+        let private = NoteRequest { private: true, ..note("Customer was rude") };
+        notes.add_note(&session, private, &author).await.unwrap();
+        assert_eq!(notes.notes(session.id, &author).await.len(), 2);
+        assert_eq!(notes.notes(session.id, &colleague).await.len(), 1);
+        assert_eq!(notes.notes(session.id, &user("admin")).await.len(), 2);
+
+        assert_eq!(
+            notes.edit_note(session.id, added.id, "Someone else's words".to_string(), &colleague).await.unwrap_err(),
+            NoteError::NotAuthor
+        );
+        let edited = notes.edit_note(session.id, added.id, "Cleared and restarted the spooler".to_string(), &author).await.unwrap();
+        assert_eq!(edited.history.0.len(), 1);
+        assert_eq!(edited.history.0[0].body, "Cleared the print spooler");
+        assert_eq!(edited.history.0[0].edited_by.as_deref(), Some("technician@example.com"));
+
+        let closeout = NoteRequest { private: true, closeout: true, ..note("Done") };
+        assert!(matches!(notes.add_note(&session, closeout, &author).await, Err(NoteError::Invalid(_))));
+        notes.add_note(&session, NoteRequest { closeout: true, ..note("Done") }, &author).await.unwrap();
+        assert!(notes.has_closeout(session.id).await);
+    }
+
+    #[test]
+    fn test_ticket_refs() {
+        assert_eq!(normalize_ticket_ref(Some("  INC-4711 ")).unwrap().as_deref(), Some("INC-4711"));
+        assert_eq!(normalize_ticket_ref(Some("   ")).unwrap(), None);
+        assert_eq!(normalize_ticket_ref(None).unwrap(), None);
+        assert!(normalize_ticket_ref(Some("INC\n4711")).is_err());
+        assert!(normalize_ticket_ref(Some(&"x".repeat(MAX_TICKET_REF_LENGTH + 1))).is_err());
+    }
+}
@@ -0,0 +1,667 @@
+//! Handing a running session to another technician
+//!
+//! The technician running a session offers it to a colleague with
+//! `POST /api/sessions/:id/transfer`. The colleague finds the offer in
+//! `GET /api/transfers` and accepts it, which checks they may control the
+//! device and mints them a token for the same session. With `shadow` set,
+//! their viewer first watches alongside the current technician and control
+//! passes when either of them sends `complete_transfer`; otherwise it passes
+//! as soon as the new viewer attaches. The control token belongs to the
+//! session, so it passes with it, and the timeline, chat and recording carry
+//! on under the same session ID. The agent is only told the name of its new
+//! operator. With `keep_original` the first technician stays on as a
+//! viewer; otherwise they are disconnected.
+//!
+//! An offer nobody accepts expires. If the current technician leaves while
+//! their colleague is shadowing, control passes at once; if they leave
+//! before the colleague attached, the session waits for the offer to be
+//! taken up and ends when it expires or is declined.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::audit::RequestContext;
+use crate::auth::{authz, jwt::{error, AuthUser}, session_tokens::{self, SessionScope}};
+use crate::models::{AuditLog, Session, SessionType};
+use crate::organizations::{self, Tenant};
+use crate::AppState;
+
+/// Seconds an offer stays open when the request names no lifetime
+const DEFAULT_TTL_SECS: i64 = 5 * 60;
+
+/// Longest an offer may stay open
+const MAX_TTL_SECS: i64 = 60 * 60;
+
+/// How long a declined or expired offer is kept, so the technicians are
+/// told what became of it instead of that it never existed
+const RETENTION_MINUTES: i64 = 60;
+
+/// How often offers are checked for their expiry
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    /// Waiting for the colleague to accept
+    Offered,
+    /// Accepted; waiting for the colleague's viewer to attach
+    Accepted,
+    /// The colleague watches alongside the current technician
+    Shadowing,
+    /// The colleague runs the session
+    Completed,
+    /// Withdrawn, declined, or left by the colleague before control passed
+    Cancelled,
+    Expired,
+}
+
+impl TransferState {
+    /// Control has not passed yet, and still may
+    pub fn is_open(self) -> bool {
+        matches!(self, TransferState::Offered | TransferState::Accepted | TransferState::Shadowing)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Transfer {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub agent_id: Uuid,
+    pub organization_id: Option<Uuid>,
+    /// Technician handing the session over
+    pub from_user: Uuid,
+    /// Technician the session is offered to
+    pub to_user: Uuid,
+    /// How the agent names the new operator, from their account on accepting
+    pub to_name: Option<String>,
+    /// Watch alongside the current technician before taking over
+    pub shadow: bool,
+    /// The current technician stays on as a viewer once control passed
+    pub keep_original: bool,
+    pub state: TransferState,
+    /// The current technician disconnected before control passed
+    pub original_left: bool,
+    pub created_at: DateTime<Utc>,
+    /// When an offer nobody took up expires; a colleague who is shadowing
+    /// is no longer held to it
+    pub expires_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Transfer {
+    /// `user_id` accepts the offer under `name`
+    pub fn accept(&mut self, user_id: Uuid, name: &str, now: DateTime<Utc>) -> Result<(), TransferError> {
+        if user_id != self.to_user {
+            return Err(TransferError::NotYours);
+        }
+        match self.state {
+            TransferState::Offered if now >= self.expires_at => Err(TransferError::Expired),
+            TransferState::Offered => {
+                self.state = TransferState::Accepted;
+                self.to_name = Some(name.to_string());
+                self.updated_at = now;
+                Ok(())
+            }
+            // Accepting twice mints another token, e.g. after a closed tab
+            TransferState::Accepted => Ok(()),
+            TransferState::Expired => Err(TransferError::Expired),
+            _ => Err(TransferError::Closed),
+        }
+    }
+
+    /// The colleague's viewer attached: they shadow, or take over at once
+    /// when they asked not to shadow or nobody is left to shadow
+    pub fn attach(&mut self, now: DateTime<Utc>) {
+        if self.state != TransferState::Accepted {
+            return;
+        }
+        self.state = if self.shadow && !self.original_left {
+            TransferState::Shadowing
+        } else {
+            TransferState::Completed
+        };
+        self.updated_at = now;
+    }
+
+    /// Control passes to the shadowing colleague
+    pub fn complete(&mut self, now: DateTime<Utc>) -> bool {
+        if self.state != TransferState::Shadowing {
+            return false;
+        }
+        self.state = TransferState::Completed;
+        self.updated_at = now;
+        true
+    }
+
+    /// The current technician disconnected. A shadowing colleague takes
+    /// over; one who has not attached yet still may until the offer expires.
+    pub fn original_left(&mut self, now: DateTime<Utc>) {
+        self.original_left = true;
+        self.updated_at = now;
+        self.complete(now);
+    }
+
+    /// Withdraw or decline the offer, or note that the colleague's viewer
+    /// left before control passed
+    pub fn cancel(&mut self, now: DateTime<Utc>) -> Result<(), TransferError> {
+        if !self.state.is_open() {
+            return Err(TransferError::Closed);
+        }
+        self.state = TransferState::Cancelled;
+        self.updated_at = now;
+        Ok(())
+    }
+
+    /// Expire an offer past its deadline that nobody attached for
+    pub fn expire(&mut self, now: DateTime<Utc>) -> bool {
+        if !matches!(self.state, TransferState::Offered | TransferState::Accepted) || now < self.expires_at {
+            return false;
+        }
+        self.state = TransferState::Expired;
+        self.updated_at = now;
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    Unknown,
+    /// Offered to, or by, another technician
+    NotYours,
+    /// Offered to the technician already running the session
+    SameUser,
+    /// The session already has an offer open
+    Busy,
+    Expired,
+    /// Already completed, cancelled or declined
+    Closed,
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::Unknown => write!(f, "unknown transfer"),
+            TransferError::NotYours => write!(f, "transfer belongs to other technicians"),
+            TransferError::SameUser => write!(f, "a session cannot be transferred to the technician running it"),
+            TransferError::Busy => write!(f, "session already has a transfer in progress"),
+            TransferError::Expired => write!(f, "transfer offer expired"),
+            TransferError::Closed => write!(f, "transfer was already completed or cancelled"),
+        }
+    }
+}
+
+impl IntoResponse for TransferError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            TransferError::Unknown => StatusCode::NOT_FOUND,
+            TransferError::NotYours => StatusCode::FORBIDDEN,
+            TransferError::SameUser => StatusCode::BAD_REQUEST,
+            TransferError::Busy | TransferError::Closed => StatusCode::CONFLICT,
+            TransferError::Expired => StatusCode::GONE,
+        };
+        error(status, self.to_string())
+    }
+}
+
+/// Transfers of active sessions, and offers that ended recently
+#[derive(Debug, Default)]
+pub struct TransferTracker {
+    transfers: RwLock<HashMap<Uuid, Transfer>>,
+}
+
+impl TransferTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offer `session` to `to_user` on behalf of its technician; a session
+    /// has one open offer at a time
+    pub async fn offer(
+        &self,
+        session: &Session,
+        to_user: Uuid,
+        shadow: bool,
+        keep_original: bool,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<Transfer, TransferError> {
+        if to_user == session.user_id {
+            return Err(TransferError::SameUser);
+        }
+        let mut transfers = self.transfers.write().await;
+        if transfers.values().any(|transfer| transfer.session_id == session.id && transfer.state.is_open()) {
+            return Err(TransferError::Busy);
+        }
+        let transfer = Transfer {
+            id: Uuid::new_v4(),
+            session_id: session.id,
+            agent_id: session.agent_id,
+            organization_id: session.organization_id,
+            from_user: session.user_id,
+            to_user,
+            to_name: None,
+            shadow,
+            keep_original,
+            state: TransferState::Offered,
+            original_left: false,
+            created_at: now,
+            expires_at: now + ttl,
+            updated_at: now,
+        };
+        transfers.insert(transfer.id, transfer.clone());
+        Ok(transfer)
+    }
+
+    pub async fn get(&self, transfer_id: Uuid) -> Option<Transfer> {
+        self.transfers.read().await.get(&transfer_id).cloned()
+    }
+
+    /// Open offers made to `user_id`, oldest first
+    pub async fn offered_to(&self, user_id: Uuid) -> Vec<Transfer> {
+        let mut offers: Vec<Transfer> = self.transfers.read().await.values()
+            .filter(|transfer| transfer.to_user == user_id && transfer.state.is_open())
+            .cloned()
+            .collect();
+        offers.sort_by_key(|transfer| transfer.created_at);
+        offers
+    }
+
+    /// Run `change` on the transfer `transfer_id`, returning it changed
+    async fn update<F>(&self, transfer_id: Uuid, change: F) -> Result<Transfer, TransferError>
+    where
+        F: FnOnce(&mut Transfer) -> Result<(), TransferError>,
+    {
+        let mut transfers = self.transfers.write().await;
+        let transfer = transfers.get_mut(&transfer_id).ok_or(TransferError::Unknown)?;
+        change(transfer)?;
+        Ok(transfer.clone())
+    }
+
+    /// Run `change` on the open transfer of `session_id`, if it has one
+    async fn update_open(&self, session_id: Uuid, change: impl FnOnce(&mut Transfer)) -> Option<Transfer> {
+        let mut transfers = self.transfers.write().await;
+        let transfer = transfers.values_mut()
+            .find(|transfer| transfer.session_id == session_id && transfer.state.is_open())?;
+        change(transfer);
+        Some(transfer.clone())
+    }
+
+    pub async fn accept(&self, transfer_id: Uuid, user_id: Uuid, name: &str, now: DateTime<Utc>) -> Result<Transfer, TransferError> {
+        self.update(transfer_id, |transfer| transfer.accept(user_id, name, now)).await
+    }
+
+    /// Withdraw the offer as the technician who made it, or decline it as
+    /// the one it was made to
+    pub async fn cancel(&self, transfer_id: Uuid, user_id: Uuid, now: DateTime<Utc>) -> Result<Transfer, TransferError> {
+        self.update(transfer_id, |transfer| {
+            if user_id != transfer.from_user && user_id != transfer.to_user {
+                return Err(TransferError::NotYours);
+            }
+            transfer.cancel(now)
+        }).await
+    }
+
+    /// A viewer of `user_id` attaches to `session_id`: the accepted transfer
+    /// to them, now shadowing or completed, or `None` if they are not
+    /// taking the session over
+    pub async fn attach(&self, session_id: Uuid, user_id: Uuid, now: DateTime<Utc>) -> Option<Transfer> {
+        let mut attached = false;
+        let transfer = self.update_open(session_id, |transfer| {
+            if transfer.to_user == user_id && transfer.state == TransferState::Accepted {
+                transfer.attach(now);
+                attached = true;
+            }
+        }).await;
+        transfer.filter(|_| attached)
+    }
+
+    /// Pass control of `session_id` to the colleague shadowing it
+    pub async fn complete(&self, session_id: Uuid, now: DateTime<Utc>) -> Option<Transfer> {
+        let mut completed = false;
+        let transfer = self.update_open(session_id, |transfer| completed = transfer.complete(now)).await;
+        transfer.filter(|_| completed)
+    }
+
+    /// The technician running `session_id` disconnected; returns its open
+    /// transfer, completed if the colleague was shadowing
+    pub async fn original_left(&self, session_id: Uuid, now: DateTime<Utc>) -> Option<Transfer> {
+        self.update_open(session_id, |transfer| transfer.original_left(now)).await
+    }
+
+    /// The shadowing viewer of `user_id` left `session_id` before control
+    /// passed to it
+    pub async fn abandon(&self, session_id: Uuid, user_id: Uuid, now: DateTime<Utc>) -> Option<Transfer> {
+        let mut abandoned = false;
+        let transfer = self.update_open(session_id, |transfer| {
+            if transfer.to_user == user_id && transfer.state == TransferState::Shadowing {
+                abandoned = transfer.cancel(now).is_ok();
+            }
+        }).await;
+        transfer.filter(|_| abandoned)
+    }
+
+    /// Whether `user_id` handed `session_id` over, so their viewer leaving
+    /// does not end it
+    pub async fn handed_off(&self, session_id: Uuid, user_id: Uuid) -> bool {
+        self.transfers.read().await.values().any(|transfer| {
+            transfer.session_id == session_id && transfer.from_user == user_id && transfer.state == TransferState::Completed
+        })
+    }
+
+    /// Expire offers past their deadline, returning them, and drop those
+    /// that ended longer ago than the retention
+    pub async fn expire(&self, now: DateTime<Utc>) -> Vec<Transfer> {
+        let mut transfers = self.transfers.write().await;
+        let expired = transfers.values_mut()
+            .filter_map(|transfer| transfer.expire(now).then(|| transfer.clone()))
+            .collect();
+        transfers.retain(|_, transfer| {
+            matches!(transfer.state, TransferState::Completed)
+                || transfer.state.is_open()
+                || transfer.updated_at + Duration::minutes(RETENTION_MINUTES) > now
+        });
+        expired
+    }
+
+    /// Forget the transfers of a session that ended
+    pub async fn session_ended(&self, session_id: Uuid) {
+        self.transfers.write().await.retain(|_, transfer| transfer.session_id != session_id);
+    }
+}
+
+// ============================================================================
+// API Handlers
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct TransferRequest {
+    /// Technician to hand the session to
+    pub to_user: Uuid,
+    /// Watch alongside the current technician before taking over
+    #[serde(default)]
+    pub shadow: bool,
+    /// The current technician stays on as a viewer
+    #[serde(default)]
+    pub keep_original: bool,
+    /// Seconds the offer stays open
+    #[serde(default)]
+    pub expires_in_secs: Option<i64>,
+}
+
+/// Offer a session to another technician
+pub async fn api_offer_transfer(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    context: RequestContext,
+    Path(session_id): Path<Uuid>,
+    Json(request): Json<TransferRequest>,
+) -> Response {
+    let ttl_secs = request.expires_in_secs.unwrap_or(DEFAULT_TTL_SECS);
+    if !(1..=MAX_TTL_SECS).contains(&ttl_secs) {
+        return error(StatusCode::BAD_REQUEST, format!("expires_in_secs must be between 1 and {}", MAX_TTL_SECS));
+    }
+    let device_manager = &app_state.device_manager;
+    let session = match device_manager.get_active_session(session_id).await {
+        Ok(session) if tenant.includes(session.organization_id) => session,
+        _ => return organizations::session_not_found(session_id).into_response(),
+    };
+    // Admins may hand over the sessions of their organization's technicians
+    let is_admin = authz::user_role(&user).is_ok_and(|role| role.is_admin());
+    if session.user_id != user.user_id && !is_admin {
+        return TransferError::NotYours.into_response();
+    }
+    if let Some(db) = &app_state.db {
+        match db.get_user_by_id(request.to_user).await {
+            Ok(Some(target)) if target.is_active => {}
+            Ok(_) => {
+                return (StatusCode::NOT_FOUND, Json(serde_json::json!({
+                    "error": format!("User not found: {}", request.to_user)
+                }))).into_response();
+            }
+            Err(e) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+                    "error": format!("Failed to look up user: {}", e)
+                }))).into_response();
+            }
+        }
+    }
+
+    let transfer = device_manager.offer_transfer(
+        &session,
+        request.to_user,
+        request.shadow,
+        request.keep_original,
+        Duration::seconds(ttl_secs),
+    ).await;
+    match transfer {
+        Ok(transfer) => {
+            device_manager.record_audit(
+                AuditLog::new("session", "session_transfer_offered")
+                    .actor(user.user_id.to_string())
+                    .agent(session.agent_id)
+                    .session(session.id)
+                    .request(&context)
+                    .details(serde_json::json!({
+                        "transfer_id": transfer.id,
+                        "to_user": transfer.to_user,
+                        "shadow": transfer.shadow,
+                    })),
+            ).await;
+            (StatusCode::CREATED, Json(transfer)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Open offers made to the caller
+pub async fn api_list_transfers(State(app_state): State<AppState>, user: AuthUser) -> Response {
+    let transfers = app_state.device_manager.transfers.offered_to(user.user_id).await;
+    Json(serde_json::json!({ "transfers": transfers })).into_response()
+}
+
+/// Accept an offer: the caller gets a token to attach to the session with
+pub async fn api_accept_transfer(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(transfer_id): Path<Uuid>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    let Some(transfer) = device_manager.transfers.get(transfer_id).await else {
+        return TransferError::Unknown.into_response();
+    };
+    if transfer.to_user != user.user_id {
+        return TransferError::NotYours.into_response();
+    }
+    let Ok(session) = device_manager.get_active_session(transfer.session_id).await else {
+        return organizations::session_not_found(transfer.session_id).into_response();
+    };
+    // The new technician needs the same access to the device as anyone
+    // starting this kind of session on it
+    let session_type = session.session_type.parse::<SessionType>().unwrap_or(SessionType::Control);
+    let needs_control = !matches!(session_type, SessionType::View);
+    if let Err(e) = authz::check_agent_permission(&app_state, &user, session.agent_id, needs_control).await {
+        return e.into_response();
+    }
+
+    let transfer = match device_manager.transfers.accept(transfer_id, user.user_id, &user.email, Utc::now()).await {
+        Ok(transfer) => transfer,
+        Err(e) => return e.into_response(),
+    };
+    let scopes = device_manager.agent_capabilities(session.agent_id).await
+        .usable_scopes(SessionScope::defaults_for(&session_type));
+    let ttl = (transfer.expires_at - Utc::now())
+        .min(Duration::minutes(session_tokens::DEFAULT_TOKEN_TTL_MINUTES as i64));
+    let (token, claims) = match device_manager.session_tokens.mint(session.id, user.user_id, scopes, ttl, Utc::now()).await {
+        Ok(token) => token,
+        Err(e) => return e.into_response(),
+    };
+    device_manager.notify_transfer(&transfer).await;
+    device_manager.record_audit(
+        AuditLog::new("session", "session_transfer_accepted")
+            .actor(user.user_id.to_string())
+            .agent(session.agent_id)
+            .session(session.id)
+            .request(&context)
+            .details(serde_json::json!({ "transfer_id": transfer.id, "from_user": transfer.from_user })),
+    ).await;
+
+    Json(serde_json::json!({
+        "transfer": transfer,
+        "session_id": session.id,
+        "token": token,
+        "token_expires_at": claims.expires_at(),
+        "scopes": claims.scopes,
+        "launch_url": session_tokens::launch_url(session.id, &token),
+    }))
+    .into_response()
+}
+
+/// Withdraw an offer, or decline one made to the caller
+pub async fn api_cancel_transfer(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    Path(transfer_id): Path<Uuid>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    match device_manager.cancel_transfer(transfer_id, user.user_id).await {
+        Ok(transfer) => {
+            let action = if transfer.to_user == user.user_id {
+                "session_transfer_declined"
+            } else {
+                "session_transfer_cancelled"
+            };
+            device_manager.record_audit(
+                AuditLog::new("session", action)
+                    .actor(user.user_id.to_string())
+                    .agent(transfer.agent_id)
+                    .session(transfer.session_id)
+                    .request(&context)
+                    .details(serde_json::json!({ "transfer_id": transfer.id })),
+            ).await;
+            Json(transfer).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(user_id: Uuid) -> Session {
+        Session {
+            id: Uuid::new_v4(),
+            agent_id: Uuid::new_v4(),
+            user_id,
+            organization_id: None,
+            session_type: "control".to_string(),
+            status: "active".to_string(),
+            started_at: Some(Utc::now()),
+            ended_at: None,
+            duration_seconds: None,
+            bytes_transferred: 0,
+            frames_captured: 0,
+            settings: sqlx::types::Json(HashMap::new()),
+            metadata: sqlx::types::Json(HashMap::new()),
+            ticket_ref: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shadowing_transfer_sequence() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let session = session(first);
+        let tracker = TransferTracker::new();
+        let now = Utc::now();
+        let ttl = Duration::minutes(5);
+
+        assert_eq!(tracker.offer(&session, first, true, false, ttl, now).await.unwrap_err(), TransferError::SameUser);
+        let transfer = tracker.offer(&session, second, true, false, ttl, now).await.unwrap();
+        assert_eq!(tracker.offer(&session, Uuid::new_v4(), true, false, ttl, now).await.unwrap_err(), TransferError::Busy);
+        assert_eq!(tracker.offered_to(second).await, vec![transfer.clone()]);
+
+        // Only the colleague accepts, and their viewer alone takes the session over
+        assert_eq!(tracker.accept(transfer.id, first, "first", now).await.unwrap_err(), TransferError::NotYours);
+        assert_eq!(tracker.attach(session.id, second, now).await, None);
+        let accepted = tracker.accept(transfer.id, second, "second@example.com", now).await.unwrap();
+        assert_eq!((accepted.state, accepted.to_name.as_deref()), (TransferState::Accepted, Some("second@example.com")));
+        assert_eq!(tracker.attach(session.id, first, now).await, None);
+        assert_eq!(tracker.attach(session.id, second, now).await.unwrap().state, TransferState::Shadowing);
+
+        // Shadowing does not run out, and control passes once
+        assert!(tracker.expire(now + Duration::hours(1)).await.is_empty());
+        assert_eq!(tracker.complete(session.id, now).await.unwrap().state, TransferState::Completed);
+        assert_eq!(tracker.complete(session.id, now).await, None);
+        assert!(tracker.handed_off(session.id, first).await);
+        assert!(!tracker.handed_off(session.id, second).await);
+        assert!(tracker.offered_to(second).await.is_empty());
+
+        tracker.session_ended(session.id).await;
+        assert_eq!(tracker.get(transfer.id).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_original_leaving_mid_transfer() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let ttl = Duration::minutes(5);
+        let tracker = TransferTracker::new();
+
+        // A shadowing colleague takes over at once
+        let shadowed = session(first);
+        let transfer = tracker.offer(&shadowed, second, true, true, ttl, now).await.unwrap();
+        tracker.accept(transfer.id, second, "second", now).await.unwrap();
+        tracker.attach(shadowed.id, second, now).await.unwrap();
+        let left = tracker.original_left(shadowed.id, now).await.unwrap();
+        assert_eq!((left.state, left.original_left), (TransferState::Completed, true));
+
+        // One who has not attached yet skips shadowing, with nobody to shadow
+        let waiting = session(first);
+        let transfer = tracker.offer(&waiting, second, true, true, ttl, now).await.unwrap();
+        assert_eq!(tracker.original_left(waiting.id, now).await.unwrap().state, TransferState::Offered);
+        tracker.accept(transfer.id, second, "second", now).await.unwrap();
+        assert_eq!(tracker.attach(waiting.id, second, now).await.unwrap().state, TransferState::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_and_declined_offers() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let ttl = Duration::minutes(5);
+        let tracker = TransferTracker::new();
+
+        let session = session(first);
+        let transfer = tracker.offer(&session, second, false, false, ttl, now).await.unwrap();
+        assert!(tracker.expire(now + Duration::minutes(4)).await.is_empty());
+        let expired = tracker.expire(now + ttl).await;
+        assert_eq!(expired.iter().map(|transfer| transfer.state).collect::<Vec<_>>(), [TransferState::Expired]);
+        assert_eq!(tracker.accept(transfer.id, second, "second", now + ttl).await.unwrap_err(), TransferError::Expired);
+
+        // Once one offer ended the session can be offered again, and only
+        // the two technicians may call it off
+        let transfer = tracker.offer(&session, second, false, false, ttl, now).await.unwrap();
+        assert_eq!(tracker.cancel(transfer.id, Uuid::new_v4(), now).await.unwrap_err(), TransferError::NotYours);
+        assert_eq!(tracker.cancel(transfer.id, second, now).await.unwrap().state, TransferState::Cancelled);
+        assert_eq!(tracker.cancel(transfer.id, first, now).await.unwrap_err(), TransferError::Closed);
+
+        // Ended offers are kept for a while, then dropped
+        tracker.expire(now + Duration::minutes(RETENTION_MINUTES - 1)).await;
+        assert!(tracker.get(transfer.id).await.is_some());
+        tracker.expire(now + Duration::minutes(RETENTION_MINUTES)).await;
+        assert_eq!(tracker.get(transfer.id).await, None);
+    }
+}
@@ -0,0 +1,703 @@
+//! Ad-hoc support codes
+//!
+//! A technician creates a short code and reads it to the end user, who runs
+//! `ghostlink-client join --code <code>`. That client registers a temporary
+//! agent that presents the code instead of an enrollment signature, and
+//! waits. When the technician opens the session from the web, the code is
+//! matched to the waiting agent and used up; the temporary agent is purged
+//! when that session ends, when it disconnects, or when the code expires
+//! before anyone connected. An enrolled agent can join a code too, when its
+//! end user types it into the tray; it stays enrolled afterwards.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::auth::{authz, jwt::{error, AuthUser}, session_tokens::{self, SessionScope}};
+use crate::models::SessionType;
+use crate::organizations::Tenant;
+use crate::relay::capabilities::Unsupported;
+use crate::relay::codecs::ViewerCapabilities;
+use crate::AppState;
+
+/// Characters codes are made of; no 0/O, 1/I/L to misread over the phone
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+/// Characters per code
+pub const CODE_LENGTH: usize = 6;
+
+/// Lifetime of a code when the technician does not ask for one
+const DEFAULT_TTL_MINUTES: i64 = 15;
+
+/// Longest lifetime a technician may request
+const MAX_TTL_MINUTES: i64 = 24 * 60;
+
+/// How often expired codes and their agents are swept
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// WebSocket close code: the support code is unknown, expired or used
+pub const CLOSE_INVALID_SUPPORT_CODE: u16 = 4404;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SupportCode {
+    pub code: String,
+    /// Technician who created it; the only one who may connect with it
+    pub created_by: Uuid,
+    /// Organization of the technician; the agent that joins is filed under it
+    pub organization_id: Option<Uuid>,
+    pub session_type: SessionType,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Temporary agent that presented the code
+    pub agent_id: Option<Uuid>,
+    /// Session the code was used for
+    pub session_id: Option<Uuid>,
+    /// The agent that joined is enrolled and is not purged when done
+    pub enrolled: bool,
+}
+
+impl SupportCode {
+    /// Waiting for the end user, or for the technician to connect
+    pub fn is_open(&self) -> bool {
+        self.session_id.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SupportCodeError {
+    Unknown,
+    Expired,
+    /// Already presented by another agent, or already used for a session
+    AlreadyUsed,
+    /// No end user has joined with it yet
+    NotJoined,
+    /// Created by another technician
+    NotYours,
+    /// Presented by an enrolled device of another organization
+    OtherOrganization,
+    /// The end user's client left before the session could start
+    AgentOffline,
+    /// The end user's client cannot serve the code's session type
+    Unsupported(Unsupported),
+}
+
+impl std::fmt::Display for SupportCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SupportCodeError::Unknown => write!(f, "unknown support code"),
+            SupportCodeError::Expired => write!(f, "support code expired"),
+            SupportCodeError::AlreadyUsed => write!(f, "support code was already used"),
+            SupportCodeError::NotJoined => write!(f, "nobody has joined with this support code yet"),
+            SupportCodeError::NotYours => write!(f, "support code belongs to another technician"),
+            SupportCodeError::OtherOrganization => write!(f, "support code belongs to another organization"),
+            SupportCodeError::AgentOffline => write!(f, "the end user's client is no longer connected"),
+            SupportCodeError::Unsupported(unsupported) => unsupported.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for SupportCodeError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            SupportCodeError::Unsupported(unsupported) => return unsupported.into_response(),
+            SupportCodeError::Unknown => StatusCode::NOT_FOUND,
+            SupportCodeError::Expired => StatusCode::GONE,
+            SupportCodeError::AlreadyUsed | SupportCodeError::NotJoined | SupportCodeError::AgentOffline => StatusCode::CONFLICT,
+            SupportCodeError::NotYours | SupportCodeError::OtherOrganization => StatusCode::FORBIDDEN,
+        };
+        error(status, self.to_string())
+    }
+}
+
+/// Uppercase `code` and drop the spaces and dashes people type into it
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// A random code of `length` characters from [`CODE_ALPHABET`]
+pub(crate) fn random_code(length: usize) -> String {
+    let rng = SystemRandom::new();
+    let mut code = String::with_capacity(length);
+    // Bytes past the last whole multiple of the alphabet would favour its start
+    let limit = (256 / CODE_ALPHABET.len() * CODE_ALPHABET.len()) as u8;
+    while code.len() < length {
+        let mut byte = [0u8; 1];
+        rng.fill(&mut byte).expect("system random number generator failed");
+        if byte[0] < limit {
+            code.push(CODE_ALPHABET[byte[0] as usize % CODE_ALPHABET.len()] as char);
+        }
+    }
+    code
+}
+
+/// Codes that are waiting or in use
+#[derive(Debug, Default)]
+pub struct SupportCodeManager {
+    codes: RwLock<HashMap<String, SupportCode>>,
+}
+
+impl SupportCodeManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a code for `created_by`, unique among the live ones
+    pub async fn create(
+        &self,
+        created_by: Uuid,
+        organization_id: Option<Uuid>,
+        session_type: SessionType,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> SupportCode {
+        let mut codes = self.codes.write().await;
+        let code = loop {
+            let code = random_code(CODE_LENGTH);
+            if !codes.contains_key(&code) {
+                break code;
+            }
+        };
+        let support_code = SupportCode {
+            code: code.clone(),
+            created_by,
+            organization_id,
+            session_type,
+            created_at: now,
+            expires_at: now + ttl,
+            agent_id: None,
+            session_id: None,
+            enrolled: false,
+        };
+        codes.insert(code, support_code.clone());
+        support_code
+    }
+
+    pub async fn get(&self, code: &str) -> Option<SupportCode> {
+        self.codes.read().await.get(&normalize_code(code)).cloned()
+    }
+
+    /// Codes created by `user_id`, oldest first
+    pub async fn created_by(&self, user_id: Uuid) -> Vec<SupportCode> {
+        let mut codes: Vec<SupportCode> = self
+            .codes
+            .read()
+            .await
+            .values()
+            .filter(|code| code.created_by == user_id)
+            .cloned()
+            .collect();
+        codes.sort_by_key(|code| code.created_at);
+        codes
+    }
+
+    /// A temporary agent presents `code`; presenting it again after a
+    /// reconnect is fine, presenting one another agent holds is not
+    pub async fn join(&self, code: &str, agent_id: Uuid, now: DateTime<Utc>) -> Result<SupportCode, SupportCodeError> {
+        let mut codes = self.codes.write().await;
+        let support_code = codes.get_mut(&normalize_code(code)).ok_or(SupportCodeError::Unknown)?;
+        Self::claim(support_code, agent_id, now)?;
+        Ok(support_code.clone())
+    }
+
+    /// Enrolled agent `agent_id` of `organization_id` presents `code`; a
+    /// code of another organization is not its to join
+    pub async fn join_enrolled(
+        &self,
+        code: &str,
+        agent_id: Uuid,
+        organization_id: Option<Uuid>,
+        now: DateTime<Utc>,
+    ) -> Result<SupportCode, SupportCodeError> {
+        let mut codes = self.codes.write().await;
+        let support_code = codes.get_mut(&normalize_code(code)).ok_or(SupportCodeError::Unknown)?;
+        if support_code.organization_id.is_some() && support_code.organization_id != organization_id {
+            return Err(SupportCodeError::OtherOrganization);
+        }
+        Self::claim(support_code, agent_id, now)?;
+        support_code.enrolled = true;
+        Ok(support_code.clone())
+    }
+
+    fn claim(support_code: &mut SupportCode, agent_id: Uuid, now: DateTime<Utc>) -> Result<(), SupportCodeError> {
+        if !support_code.is_open() || support_code.agent_id.is_some_and(|joined| joined != agent_id) {
+            return Err(SupportCodeError::AlreadyUsed);
+        }
+        if now >= support_code.expires_at {
+            return Err(SupportCodeError::Expired);
+        }
+        support_code.agent_id = Some(agent_id);
+        Ok(())
+    }
+
+    /// The technician connects with `code`; returns it with the agent to
+    /// start the session on. Call [`Self::attach_session`] once it exists.
+    pub async fn redeem(&self, code: &str, user_id: Uuid, is_admin: bool, now: DateTime<Utc>) -> Result<SupportCode, SupportCodeError> {
+        let codes = self.codes.read().await;
+        let support_code = codes.get(&normalize_code(code)).ok_or(SupportCodeError::Unknown)?;
+        if support_code.created_by != user_id && !is_admin {
+            return Err(SupportCodeError::NotYours);
+        }
+        if !support_code.is_open() {
+            return Err(SupportCodeError::AlreadyUsed);
+        }
+        if now >= support_code.expires_at {
+            return Err(SupportCodeError::Expired);
+        }
+        if support_code.agent_id.is_none() {
+            return Err(SupportCodeError::NotJoined);
+        }
+        Ok(support_code.clone())
+    }
+
+    /// Use up `code` for `session_id`; fails if another session got it first
+    pub async fn attach_session(&self, code: &str, session_id: Uuid) -> Result<(), SupportCodeError> {
+        let mut codes = self.codes.write().await;
+        let support_code = codes.get_mut(&normalize_code(code)).ok_or(SupportCodeError::Unknown)?;
+        if !support_code.is_open() {
+            return Err(SupportCodeError::AlreadyUsed);
+        }
+        support_code.session_id = Some(session_id);
+        Ok(())
+    }
+
+    /// Drop the code used for `session_id`, returning it
+    pub async fn session_ended(&self, session_id: Uuid) -> Option<SupportCode> {
+        let mut codes = self.codes.write().await;
+        let code = codes.values().find(|code| code.session_id == Some(session_id))?.code.clone();
+        codes.remove(&code)
+    }
+
+    /// `agent_id` disconnected. A code it used for a session is dropped and
+    /// returned, since that session ended with it; a code still waiting is
+    /// kept so the agent can reconnect before it expires.
+    pub async fn agent_left(&self, agent_id: Uuid) -> Option<SupportCode> {
+        let mut codes = self.codes.write().await;
+        let code = codes
+            .values()
+            .find(|code| code.agent_id == Some(agent_id) && !code.is_open())?
+            .code
+            .clone();
+        codes.remove(&code)
+    }
+
+    /// Drop codes that expired before a session was started with them
+    pub async fn take_expired(&self, now: DateTime<Utc>) -> Vec<SupportCode> {
+        let mut codes = self.codes.write().await;
+        let expired: Vec<String> = codes
+            .values()
+            .filter(|code| code.is_open() && now >= code.expires_at)
+            .map(|code| code.code.clone())
+            .collect();
+        expired.iter().filter_map(|code| codes.remove(code)).collect()
+    }
+}
+
+// ============================================================================
+// API Handlers
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSupportCodeRequest {
+    #[serde(default)]
+    pub ttl_minutes: Option<i64>,
+    #[serde(default)]
+    pub session_type: Option<SessionType>,
+}
+
+/// Create a support code for the caller
+pub async fn api_create_support_code(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Json(request): Json<CreateSupportCodeRequest>,
+) -> Response {
+    let ttl_minutes = request.ttl_minutes.unwrap_or(DEFAULT_TTL_MINUTES);
+    if !(1..=MAX_TTL_MINUTES).contains(&ttl_minutes) {
+        return error(StatusCode::BAD_REQUEST, format!("ttl_minutes must be between 1 and {}", MAX_TTL_MINUTES));
+    }
+
+    let session_type = request.session_type.unwrap_or(SessionType::Adhoc);
+    let code = app_state
+        .device_manager
+        .support_codes
+        .create(user.user_id, tenant.organization_id(), session_type, Duration::minutes(ttl_minutes), Utc::now())
+        .await;
+    tracing::info!("Support code {} created by {} for {} minutes", code.code, user.email, ttl_minutes);
+    (StatusCode::CREATED, Json(code)).into_response()
+}
+
+/// The caller's live support codes
+pub async fn api_list_support_codes(State(app_state): State<AppState>, user: AuthUser) -> Response {
+    let codes = app_state.device_manager.support_codes.created_by(user.user_id).await;
+    Json(serde_json::json!({ "codes": codes })).into_response()
+}
+
+/// A support code and whether anyone joined with it yet, for the web to poll
+pub async fn api_get_support_code(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(code): Path<String>,
+) -> Response {
+    let device_manager = &app_state.device_manager;
+    let Some(support_code) = device_manager.support_codes.get(&code).await else {
+        return SupportCodeError::Unknown.into_response();
+    };
+    // Admins see the codes of their own organization's technicians
+    let is_admin = authz::user_role(&user).is_ok_and(|role| role.is_admin())
+        && tenant.includes(support_code.organization_id);
+    if support_code.created_by != user.user_id && !is_admin {
+        return SupportCodeError::NotYours.into_response();
+    }
+
+    let agent = match support_code.agent_id {
+        Some(agent_id) => device_manager.get_agent(agent_id).await.map(|(agent, _)| agent),
+        None => None,
+    };
+    Json(serde_json::json!({
+        "code": support_code,
+        "joined": agent.is_some(),
+        "agent": agent,
+    }))
+    .into_response()
+}
+
+/// Optional body of a connect request
+#[derive(Debug, Default, Deserialize)]
+pub struct ConnectSupportCodeRequest {
+    /// Codecs, H.264 profiles and resolution the technician's viewer decodes
+    #[serde(default)]
+    pub capabilities: Option<ViewerCapabilities>,
+}
+
+/// Start the session on the agent that joined with a code, using it up
+pub async fn api_connect_support_code(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    tenant: Tenant,
+    Path(code): Path<String>,
+    body: Option<Json<ConnectSupportCodeRequest>>,
+) -> Response {
+    let request = body.map(|Json(request)| request).unwrap_or_default();
+    if let Some(Err(message)) = request.capabilities.as_ref().map(ViewerCapabilities::validate) {
+        return error(StatusCode::BAD_REQUEST, message);
+    }
+
+    let device_manager = &app_state.device_manager;
+    let code_organization = device_manager.support_codes.get(&code).await.and_then(|code| code.organization_id);
+    let is_admin = authz::user_role(&user).is_ok_and(|role| role.is_admin()) && tenant.includes(code_organization);
+    let session_id = match device_manager.start_support_session(&code, user.user_id, &user.email, is_admin, request.capabilities).await {
+        Ok(session_id) => session_id,
+        Err(e) => return e.into_response(),
+    };
+
+    // The viewer attaches with a token like any other session
+    let session_type = device_manager.support_codes.get(&code).await
+        .map_or(SessionType::Adhoc, |code| code.session_type);
+    let token = device_manager.session_tokens.mint(
+        session_id,
+        user.user_id,
+        SessionScope::defaults_for(&session_type),
+        Duration::minutes(session_tokens::DEFAULT_TOKEN_TTL_MINUTES as i64),
+        Utc::now(),
+    ).await;
+    match token {
+        Ok((token, claims)) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({
+                "status": "success",
+                "session_id": session_id,
+                "token": token,
+                "token_expires_at": claims.expires_at(),
+                "scopes": claims.scopes,
+                "launch_url": session_tokens::launch_url(session_id, &token),
+            })),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_manager::{DeviceManager, DeviceRegistration};
+    use crate::relay::outbound::{self, testing::texts, OutboundReceiver};
+    use axum::extract::ws::Message;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_codes_are_readable_and_unique() {
+        let codes: HashSet<String> = (0..2000).map(|_| random_code(CODE_LENGTH)).collect();
+        assert_eq!(codes.len(), 2000);
+        assert!(codes.iter().all(|code| code.len() == CODE_LENGTH && code.bytes().all(|c| CODE_ALPHABET.contains(&c))));
+        assert_eq!(normalize_code(" abc-12 3"), "ABC123");
+    }
+
+    #[tokio::test]
+    async fn test_codes_expire() {
+        let manager = SupportCodeManager::new();
+        let (technician, agent) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let code = manager.create(technician, None, SessionType::Adhoc, Duration::minutes(5), now).await;
+        let later = now + Duration::minutes(5);
+
+        assert_eq!(manager.join(&code.code, agent, later).await.unwrap_err(), SupportCodeError::Expired);
+        assert!(manager.take_expired(now).await.is_empty());
+        manager.join(&code.code.to_lowercase(), agent, now).await.unwrap();
+        assert_eq!(manager.redeem(&code.code, technician, false, later).await.unwrap_err(), SupportCodeError::Expired);
+
+        let expired = manager.take_expired(later).await;
+        assert_eq!(expired.iter().map(|code| code.agent_id).collect::<Vec<_>>(), vec![Some(agent)]);
+        assert!(manager.get(&code.code).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_codes_are_single_use() {
+        let manager = SupportCodeManager::new();
+        let (technician, agent) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let code = manager.create(technician, None, SessionType::Adhoc, Duration::minutes(5), now).await;
+
+        assert_eq!(manager.redeem(&code.code, technician, false, now).await.unwrap_err(), SupportCodeError::NotJoined);
+        manager.join(&code.code, agent, now).await.unwrap();
+        manager.join(&code.code, agent, now).await.expect("the same agent may reconnect");
+        assert_eq!(manager.join(&code.code, Uuid::new_v4(), now).await.unwrap_err(), SupportCodeError::AlreadyUsed);
+
+        assert_eq!(manager.redeem(&code.code, Uuid::new_v4(), false, now).await.unwrap_err(), SupportCodeError::NotYours);
+        assert_eq!(manager.redeem(&code.code, technician, false, now).await.unwrap().agent_id, Some(agent));
+        let session_id = Uuid::new_v4();
+        manager.attach_session(&code.code, session_id).await.unwrap();
+        assert_eq!(manager.redeem(&code.code, technician, false, now).await.unwrap_err(), SupportCodeError::AlreadyUsed);
+        assert_eq!(manager.attach_session(&code.code, Uuid::new_v4()).await.unwrap_err(), SupportCodeError::AlreadyUsed);
+        assert_eq!(manager.join(&code.code, agent, now).await.unwrap_err(), SupportCodeError::AlreadyUsed);
+
+        // In use, it no longer expires
+        assert!(manager.take_expired(now + Duration::hours(1)).await.is_empty());
+        assert_eq!(manager.session_ended(session_id).await.unwrap().code, code.code);
+        assert_eq!(manager.redeem(&code.code, technician, false, now).await.unwrap_err(), SupportCodeError::Unknown);
+    }
+
+    async fn join(device_manager: &DeviceManager, code: &str) -> (Uuid, OutboundReceiver) {
+        let agent_id = Uuid::new_v4();
+        device_manager.support_codes.join(code, agent_id, Utc::now()).await.unwrap();
+        let (tx, rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "visitor-laptop".to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: Some(agent_id.to_string()),
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        device_manager.register_device(registration, tx).await.unwrap();
+        (agent_id, rx)
+    }
+
+    #[tokio::test]
+    async fn test_agent_is_purged_when_the_session_ends() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let technician = Uuid::new_v4();
+        let code = device_manager.support_codes.create(technician, None, SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        let (agent_id, agent_rx) = join(&device_manager, &code.code).await;
+
+        let session_id = device_manager.start_support_session(&code.code, technician, "tech@example.com", false, None).await.unwrap();
+        let session = device_manager.get_session(session_id).await.unwrap();
+        assert_eq!((session.agent_id, session.session_type.as_str()), (agent_id, "adhoc"));
+        assert!(matches!(
+            device_manager.start_support_session(&code.code, technician, "tech@example.com", false, None).await,
+            Err(SupportCodeError::AlreadyUsed)
+        ));
+
+        device_manager.end_session(session_id).await.unwrap();
+        assert!(device_manager.get_agent(agent_id).await.is_none(), "the temporary agent is gone");
+        let kinds: Vec<_> = texts(&agent_rx).await.iter().map(|text| text["type"].as_str().unwrap_or("").to_string()).collect();
+        assert_eq!(kinds, ["SessionRequest", "SessionEnd", "SupportCodeClosed"]);
+    }
+
+    #[tokio::test]
+    async fn test_enrolled_agent_joins_from_the_tray() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let technician = Uuid::new_v4();
+        let (tx, agent_rx) = outbound::channel();
+        let registration = DeviceRegistration {
+            name: None,
+            hostname: "desk-12".to_string(),
+            platform: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: Vec::new(),
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let agent_id = device_manager.register_device(registration, tx).await.unwrap();
+        let join = |code: &str| {
+            let message = serde_json::json!({ "type": "JoinSupportCode", "code": code });
+            let device_manager = Arc::clone(&device_manager);
+            async move {
+                crate::relay::handle_agent_message(&device_manager, &agent_id.to_string(), Message::Text(message.to_string())).await.unwrap();
+            }
+        };
+
+        // Another organization's code is not this device's to join
+        let foreign = device_manager.support_codes.create(technician, Some(Uuid::new_v4()), SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        join(&foreign.code).await;
+        let closed = texts(&agent_rx).await.remove(0);
+        assert_eq!((closed["type"].as_str(), closed["reason"].as_str()), (Some("SupportCodeClosed"), Some("support code belongs to another organization")));
+
+        let code = device_manager.support_codes.create(technician, None, SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        join(&code.code.to_lowercase()).await;
+        let accepted = texts(&agent_rx).await.remove(0);
+        assert_eq!((accepted["type"].as_str(), accepted["code"].as_str()), (Some("SupportCodeAccepted"), Some(code.code.as_str())));
+        assert!(device_manager.support_codes.get(&code.code).await.unwrap().enrolled);
+
+        // The session ends like any support session, but the device stays
+        let session_id = device_manager.start_support_session(&code.code, technician, "tech@example.com", false, None).await.unwrap();
+        device_manager.end_session(session_id).await.unwrap();
+        assert!(device_manager.get_agent(agent_id).await.is_some_and(|(_, online)| online));
+        let kinds: Vec<_> = texts(&agent_rx).await.iter().map(|text| text["type"].as_str().unwrap_or("").to_string()).collect();
+        assert_eq!(kinds, ["SessionRequest", "SessionEnd", "SupportCodeClosed"]);
+    }
+
+    #[tokio::test]
+    async fn test_codec_negotiation_goes_through_the_relay() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let technician = Uuid::new_v4();
+        let code = device_manager.support_codes.create(technician, None, SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        let (_, agent_rx) = join(&device_manager, &code.code).await;
+
+        // A browser without HEVC announces what it decodes with the request
+        let capabilities = ViewerCapabilities {
+            codecs: vec!["h264".to_string(), "jpeg".to_string()],
+            h264_profiles: vec!["baseline".to_string(), "main".to_string()],
+            max_width: Some(3840),
+            max_height: Some(2160),
+            tiles: true,
+        };
+        let session_id = device_manager
+            .start_support_session(&code.code, technician, "tech@example.com", false, Some(capabilities.clone()))
+            .await
+            .unwrap();
+        let request = texts(&agent_rx).await.remove(0);
+        assert_eq!(request["type"], "SessionRequest");
+        assert_eq!(serde_json::from_value::<ViewerCapabilities>(request["capabilities"].clone()).unwrap(), capabilities);
+        let session = device_manager.get_session(session_id).await.unwrap();
+        assert_eq!(session.settings["viewer_capabilities"]["codecs"], serde_json::json!(["h264", "jpeg"]));
+
+        // The agent's pick reaches the viewer before any frame
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        device_manager.attach_session_channel(session_id, viewer_tx).await;
+        let config = serde_json::json!({ "encoder": "nvenc-h264", "codec": "h264", "width": 1920, "height": 1080 });
+        device_manager.record_stream_config(session_id, config.clone()).await.unwrap();
+        let confirmed = texts(&viewer_rx).await.into_iter().find(|text| text["type"] == "StreamConfig").unwrap();
+        assert_eq!(confirmed["config"], config);
+        assert_eq!(device_manager.get_session(session_id).await.unwrap().settings["stream_config"], config);
+
+        // A decode failure asks the agent to step down, and the session stays
+        device_manager.downgrade_stream(session_id, Some("decoder error")).await.unwrap();
+        let downgrade = texts(&agent_rx).await.remove(0);
+        assert_eq!((downgrade["type"].as_str(), downgrade["reason"].as_str()), (Some("StreamDowngrade"), Some("decoder error")));
+        assert!(device_manager.get_session(session_id).await.is_some_and(|session| session.status != "ended"));
+    }
+
+    #[tokio::test]
+    async fn test_session_banner_gates_the_session() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let branding = &device_manager.branding_manager;
+        let mut config = branding.get_branding_config(None).await;
+        config.session_banner = crate::branding::SessionBannerPolicy {
+            enabled: true,
+            acknowledgment_required: true,
+            acknowledgment_timeout_secs: 60,
+        };
+        branding.update_branding_config(None, config).await.unwrap();
+        let technician = Uuid::new_v4();
+
+        // The banner rides with the request, themed with the branding colors
+        let code = device_manager.support_codes.create(technician, None, SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        let (agent_id, agent_rx) = join(&device_manager, &code.code).await;
+        let session_id = device_manager.start_support_session(&code.code, technician, "tech@example.com", false, None).await.unwrap();
+        let banner = texts(&agent_rx).await.remove(0)["banner"].clone();
+        assert_eq!((banner["acknowledgment_required"].as_bool(), banner["acknowledgment_timeout_secs"].as_u64()), (Some(true), Some(60)));
+        assert_eq!(banner["colors"]["background"], "#0d6efd");
+
+        let acknowledged = serde_json::json!({ "type": "BannerAcknowledged", "session_id": session_id, "banner_id": banner["id"] });
+        crate::relay::handle_agent_message(&device_manager, &agent_id.to_string(), Message::Text(acknowledged.to_string())).await.unwrap();
+        let stored = branding.get_session_banner(session_id).await.unwrap();
+        assert_eq!(stored.acknowledged_by, [agent_id.to_string()]);
+
+        // A decline ends the session and tells the viewer why
+        let code = device_manager.support_codes.create(technician, None, SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        let (agent_id, agent_rx) = join(&device_manager, &code.code).await;
+        let session_id = device_manager.start_support_session(&code.code, technician, "tech@example.com", false, None).await.unwrap();
+        let (viewer_tx, viewer_rx) = outbound::channel();
+        device_manager.attach_session_channel(session_id, viewer_tx).await;
+        texts(&agent_rx).await;
+
+        let cancelled = serde_json::json!({ "type": "SessionCancelled", "session_id": session_id, "reason": "banner_declined" });
+        crate::relay::handle_agent_message(&device_manager, &agent_id.to_string(), Message::Text(cancelled.to_string())).await.unwrap();
+        assert_eq!(device_manager.get_session(session_id).await.unwrap().status, "ended");
+        let told = texts(&viewer_rx).await.into_iter().find(|text| text["type"] == "SessionCancelled").unwrap();
+        assert_eq!(told["reason"], "banner_declined");
+    }
+
+    #[tokio::test]
+    async fn test_consent_policy_reaches_the_agent() {
+        let config = crate::audit::AuditFileConfig {
+            dir: std::env::temp_dir().join(format!("ghostlink-consent-audit-{}", Uuid::new_v4())),
+            ..Default::default()
+        };
+        let device_manager = Arc::new(
+            DeviceManager::new().with_audit_file(Arc::new(crate::audit::AuditFile::new(&config))),
+        );
+        let technician = Uuid::new_v4();
+        let code = device_manager.support_codes.create(technician, None, SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        let (agent_id, agent_rx) = join(&device_manager, &code.code).await;
+        let document = crate::policies::PolicyDocument { consent_required: Some(true), ..Default::default() };
+        device_manager.create_policy(crate::organizations::Tenant::All, crate::policies::PolicyScope::Device, Some(agent_id), document).await.unwrap();
+
+        device_manager.start_support_session(&code.code, technician, "tech@example.com", false, None).await.unwrap();
+        let request = texts(&agent_rx).await.into_iter().find(|text| text["type"] == "SessionRequest").unwrap();
+        assert_eq!(request["requires_consent"], true);
+
+        // Unattended sessions skip the prompt, on the record
+        let session_id = Uuid::new_v4();
+        assert!(!device_manager.requires_consent(agent_id, session_id, "backstage").await);
+        let filter = crate::audit::AuditFilter { action: Some("consent_bypassed".to_string()), ..Default::default() };
+        let (entries, _) = device_manager.query_audit(&filter, 10, 0).await.unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.session_id).collect::<Vec<_>>(), [Some(session_id)]);
+
+        tokio::fs::remove_dir_all(&config.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_codes_purge_their_agents() {
+        let device_manager = Arc::new(DeviceManager::new());
+        let code = device_manager.support_codes.create(Uuid::new_v4(), None, SessionType::Adhoc, Duration::minutes(5), Utc::now()).await;
+        let (agent_id, agent_rx) = join(&device_manager, &code.code).await;
+
+        assert_eq!(device_manager.expire_support_codes(Utc::now()).await, 0);
+        assert_eq!(device_manager.expire_support_codes(Utc::now() + Duration::minutes(6)).await, 1);
+        assert!(device_manager.get_agent(agent_id).await.is_none());
+        assert_eq!(texts(&agent_rx).await[0]["reason"], "expired");
+    }
+}
@@ -0,0 +1,289 @@
+//! Prometheus metrics for operations dashboards
+//!
+//! [`Metrics`] keeps its own recorder rather than installing a global one, so
+//! each server (and each test) has separate series. The relay counts
+//! messages, frames, bytes and socket errors as they happen, and
+//! [`track_requests`] times every API route. Device, session and queue gauges,
+//! and the throttling counters, are read from the `DeviceManager` when
+//! `GET /metrics` is scraped.
+//!
+//! Label values only come from small fixed sets: route templates, session
+//! types, queue classes and peer kinds. They are never session or device IDs.
+
+use axum::{
+    extract::{ws::Message, MatchedPath, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{Counter, Gauge, Histogram, Key, Label, Level, Metadata, Recorder, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+use ring::digest::{digest, SHA256};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::device_manager::DeviceManager;
+use crate::organizations::Tenant;
+use crate::relay::idle::SESSION_TYPES;
+use crate::AppState;
+
+const METADATA: Metadata<'static> = Metadata::new(module_path!(), Level::INFO, Some(module_path!()));
+
+const RELAY_MESSAGES: &str = "ghostlink_relay_messages_total";
+const RELAY_BYTES: &str = "ghostlink_relay_bytes_total";
+const RELAY_FRAMES: &str = "ghostlink_relay_frames_total";
+const FRAME_DELIVERIES: &str = "ghostlink_relay_frame_deliveries_total";
+const WEBSOCKET_ERRORS: &str = "ghostlink_websocket_errors_total";
+const HTTP_DURATION: &str = "ghostlink_http_request_duration_seconds";
+const DEVICES_ONLINE: &str = "ghostlink_devices_online";
+const SESSIONS_ACTIVE: &str = "ghostlink_sessions_active";
+const QUEUE_DEPTH: &str = "ghostlink_outbound_queue_depth";
+const QUEUE_DROPPED: &str = "ghostlink_outbound_queue_dropped";
+const WEBSOCKETS_OPEN: &str = "ghostlink_websocket_connections";
+const WEBSOCKETS_REFUSED: &str = "ghostlink_websocket_refused_total";
+const AUTH_FAILURES: &str = "ghostlink_auth_failures_total";
+const AUTH_LOCKOUTS: &str = "ghostlink_auth_lockouts_total";
+
+/// Upper bounds of the request latency buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Outbound queue classes, in `OutboundStats` order
+const QUEUE_CLASSES: [&str; 4] = ["critical", "high", "normal", "low"];
+
+/// Which end of the relay a socket belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Agent,
+    Viewer,
+}
+
+impl Peer {
+    fn as_str(self) -> &'static str {
+        match self {
+            Peer::Agent => "agent",
+            Peer::Viewer => "viewer",
+        }
+    }
+}
+
+/// The server's metric series and their Prometheus rendering
+pub struct Metrics {
+    recorder: PrometheusRecorder,
+    handle: PrometheusHandle,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full(HTTP_DURATION.to_string()), LATENCY_BUCKETS)
+            .expect("latency buckets are not empty")
+            .build_recorder();
+        let handle = recorder.handle();
+        let metrics = Self { recorder, handle };
+        metrics.describe();
+        metrics
+    }
+
+    fn describe(&self) {
+        let counters = [
+            (RELAY_MESSAGES, Unit::Count, "Messages received on relay sockets"),
+            (RELAY_BYTES, Unit::Bytes, "Payload bytes received from and sent to relay sockets"),
+            (RELAY_FRAMES, Unit::Count, "Screen frames received from agents"),
+            (FRAME_DELIVERIES, Unit::Count, "Screen frames queued to viewers"),
+            (WEBSOCKET_ERRORS, Unit::Count, "Relay sockets that ended with a protocol or I/O error"),
+            (WEBSOCKETS_REFUSED, Unit::Count, "WebSockets refused by the connection caps"),
+            (AUTH_FAILURES, Unit::Count, "Failed or throttled authentication attempts"),
+            (AUTH_LOCKOUTS, Unit::Count, "Accounts locked out after failed logins"),
+        ];
+        for (name, unit, description) in counters {
+            self.recorder.describe_counter(name.into(), Some(unit), description.into());
+        }
+        let gauges = [
+            (DEVICES_ONLINE, Unit::Count, "Agents connected to the relay"),
+            (SESSIONS_ACTIVE, Unit::Count, "Open sessions by type"),
+            (QUEUE_DEPTH, Unit::Count, "Messages waiting in outbound queues by class"),
+            (QUEUE_DROPPED, Unit::Count, "Messages dropped by the outbound queues of open connections, by class"),
+            (WEBSOCKETS_OPEN, Unit::Count, "Open relay and session WebSockets"),
+        ];
+        for (name, unit, description) in gauges {
+            self.recorder.describe_gauge(name.into(), Some(unit), description.into());
+        }
+        self.recorder.describe_histogram(HTTP_DURATION.into(), Some(Unit::Seconds), "API request latency by route".into());
+    }
+
+    fn counter(&self, name: &'static str, labels: &[(&'static str, &'static str)]) -> Counter {
+        self.recorder.register_counter(&key(name, labels), &METADATA)
+    }
+
+    fn gauge(&self, name: &'static str, labels: &[(&'static str, &'static str)]) -> Gauge {
+        self.recorder.register_gauge(&key(name, labels), &METADATA)
+    }
+
+    /// Count a message received from a relay socket
+    pub fn message_received(&self, from: Peer, message: &Message) {
+        let payload = match message {
+            Message::Text(_) => "text",
+            Message::Binary(_) => "binary",
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => "control",
+        };
+        self.counter(RELAY_MESSAGES, &[("peer", from.as_str()), ("payload", payload)]).increment(1);
+        self.counter(RELAY_BYTES, &[("peer", from.as_str()), ("direction", "in")])
+            .increment(payload_len(message) as u64);
+    }
+
+    /// Count a message written to a relay socket
+    pub fn message_sent(&self, to: Peer, message: &Message) {
+        self.counter(RELAY_BYTES, &[("peer", to.as_str()), ("direction", "out")])
+            .increment(payload_len(message) as u64);
+    }
+
+    /// Count a screen frame and the viewers it was queued to
+    pub fn frame_relayed(&self, viewers: usize) {
+        self.counter(RELAY_FRAMES, &[]).increment(1);
+        self.counter(FRAME_DELIVERIES, &[]).increment(viewers as u64);
+    }
+
+    pub fn websocket_error(&self, peer: Peer) {
+        self.counter(WEBSOCKET_ERRORS, &[("peer", peer.as_str())]).increment(1);
+    }
+
+    /// Time an API request by its route template
+    pub fn request_finished(&self, method: &Method, route: &str, status: StatusCode, elapsed: Duration) {
+        let status = match status.as_u16() {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx",
+        };
+        let labels = vec![
+            Label::new("method", method.as_str().to_string()),
+            Label::new("route", route.to_string()),
+            Label::new("status", status),
+        ];
+        let histogram: Histogram = self.recorder.register_histogram(&Key::from_parts(HTTP_DURATION, labels), &METADATA);
+        histogram.record(elapsed.as_secs_f64());
+    }
+
+    /// Refresh the gauges from the device manager and render every series
+    pub async fn render(&self, device_manager: &DeviceManager) -> String {
+        let stats = device_manager.get_stats(Tenant::All).await;
+        self.gauge(DEVICES_ONLINE, &[]).set(stats.connected_devices as f64);
+        let mut other = stats.active_sessions;
+        for session_type in SESSION_TYPES {
+            let open = stats.sessions_by_type.get(session_type).copied().unwrap_or(0);
+            other = other.saturating_sub(open);
+            self.gauge(SESSIONS_ACTIVE, &[("session_type", session_type)]).set(open as f64);
+        }
+        self.gauge(SESSIONS_ACTIVE, &[("session_type", "other")]).set(other as f64);
+        for (class, name) in QUEUE_CLASSES.iter().enumerate() {
+            let depth: usize = stats.outbound_queues.values().map(|queue| queue.depth[class]).sum();
+            let dropped: u64 = stats.outbound_queues.values().map(|queue| queue.dropped[class]).sum();
+            self.gauge(QUEUE_DEPTH, &[("class", name)]).set(depth as f64);
+            self.gauge(QUEUE_DROPPED, &[("class", name)]).set(dropped as f64);
+        }
+
+        // The limiter lives as long as the server, so its totals only grow
+        let limits = &stats.rate_limiting;
+        self.gauge(WEBSOCKETS_OPEN, &[]).set(limits.open_connections as f64);
+        self.counter(WEBSOCKETS_REFUSED, &[("reason", "per_address")]).absolute(limits.refused_connections);
+        self.counter(WEBSOCKETS_REFUSED, &[("reason", "server_busy")]).absolute(limits.busy_refusals);
+        self.counter(AUTH_FAILURES, &[("reason", "invalid_credentials")]).absolute(limits.failed_logins);
+        self.counter(AUTH_FAILURES, &[("reason", "throttled")]).absolute(limits.throttled_requests);
+        self.counter(AUTH_LOCKOUTS, &[]).absolute(limits.lockouts);
+
+        self.handle.render()
+    }
+}
+
+fn key(name: &'static str, labels: &[(&'static str, &'static str)]) -> Key {
+    Key::from_parts(name, labels.iter().map(|&(label, value)| Label::new(label, value)).collect::<Vec<_>>())
+}
+
+fn payload_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(_) => 0,
+    }
+}
+
+/// Middleware timing every request by the route it matched
+pub async fn track_requests(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let route = request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string());
+    let method = request.method().clone();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    // Unmatched paths are whatever the client sent; leave them out
+    if let Some(route) = route {
+        metrics.request_finished(&method, &route, response.status(), started.elapsed());
+    }
+    response
+}
+
+/// Prometheus scrape endpoint; asks for `METRICS_TOKEN` as a bearer token
+/// when one is configured
+pub async fn api_metrics(State(app_state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(token) = &app_state.config.metrics_token {
+        let presented = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let matches = presented.is_some_and(|presented| {
+            digest(&SHA256, presented.as_bytes()).as_ref() == digest(&SHA256, token.as_bytes()).as_ref()
+        });
+        if !matches {
+            return (StatusCode::UNAUTHORIZED, "metrics token required\n").into_response();
+        }
+    }
+
+    let body = app_state.metrics.render(&app_state.device_manager).await;
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_requests_are_timed_by_route_template() {
+        let metrics = Arc::new(Metrics::new());
+        let app = Router::new()
+            .route("/devices/:id", get(|| async { "ok" }))
+            .route_layer(axum::middleware::from_fn_with_state(metrics.clone(), track_requests));
+        for uri in ["/devices/a", "/devices/b", "/unknown/path"] {
+            let request = axum::http::Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let body = metrics.render(&DeviceManager::new()).await;
+        assert!(body.contains(
+            "ghostlink_http_request_duration_seconds_count{method=\"GET\",route=\"/devices/:id\",status=\"2xx\"} 2"
+        ));
+        assert!(!body.contains("/unknown/path"));
+        assert!(!body.contains("/devices/a"));
+    }
+
+    #[tokio::test]
+    async fn test_recorders_are_separate() {
+        let (first, second) = (Metrics::new(), Metrics::new());
+        first.frame_relayed(3);
+        first.websocket_error(Peer::Viewer);
+        first.message_sent(Peer::Viewer, &Message::Text("hello".to_string()));
+
+        let device_manager = DeviceManager::new();
+        let body = first.render(&device_manager).await;
+        assert!(body.contains("ghostlink_relay_frame_deliveries_total 3"));
+        assert!(body.contains("ghostlink_websocket_errors_total{peer=\"viewer\"} 1"));
+        assert!(body.contains("ghostlink_relay_bytes_total{peer=\"viewer\",direction=\"out\"} 5"));
+        assert!(!second.render(&device_manager).await.contains("ghostlink_relay_frames_total 1"));
+    }
+}
@@ -13,7 +13,11 @@ use tokio::sync::{RwLock, mpsc};
 use uuid::Uuid;
 use tracing::{info, warn, debug, error};
 
-use crate::AppState;
+use crate::api::session_in_tenant;
+use crate::audit::RequestContext;
+use crate::organizations::{self, Tenant};
+use crate::relay::capabilities::AgentCapability;
+use crate::{device_manager::DeviceManager, models::AuditLog, AppState};
 
 /// ScreenConnect-style terminal manager for web-based command execution
 pub struct TerminalManager {
@@ -615,8 +619,10 @@ impl TerminalManager {
 pub async fn handle_terminal_websocket(
     socket: WebSocket,
     session_id: Uuid,
-    terminal_manager: Arc<TerminalManager>,
+    device_manager: Arc<DeviceManager>,
+    context: RequestContext,
 ) {
+    let terminal_manager = device_manager.terminal_manager.clone();
     info!("Starting WebSocket terminal session {}", session_id);
     
     let (mut sender, mut receiver) = socket.split();
@@ -641,7 +647,17 @@ pub async fn handle_terminal_websocket(
                         match terminal_msg.message_type {
                             TerminalMessageType::Input => {
                                 if let Some(command) = terminal_msg.data.get("command").and_then(|c| c.as_str()) {
-                                    match terminal_manager.execute_command(session_id, command.to_string()).await {
+                                    let result = terminal_manager.execute_command(session_id, command.to_string()).await;
+                                    device_manager.record_audit(
+                                        AuditLog::new("terminal", "command_executed")
+                                            .session(session_id)
+                                            .request(&context)
+                                            .details(serde_json::json!({
+                                                "command": command,
+                                                "success": result.is_ok(),
+                                            })),
+                                    ).await;
+                                    match result {
                                         Ok(output) => {
                                             let response = TerminalMessage {
                                                 message_type: TerminalMessageType::Output,
@@ -696,19 +712,47 @@ pub async fn handle_terminal_websocket(
 /// Create terminal session
 pub async fn api_create_terminal_session(
     State(app_state): State<AppState>,
+    tenant: Tenant,
     Path(client_session_id): Path<Uuid>,
+    context: RequestContext,
     Json(request): Json<CreateTerminalRequest>,
 ) -> Response {
+    if !session_in_tenant(&app_state, tenant, client_session_id).await {
+        return organizations::session_not_found(client_session_id).into_response();
+    }
+    // Agents without PTY support would never answer the terminal
+    if let Some(client_session) = app_state.device_manager.get_session(client_session_id).await {
+        let capabilities = app_state.device_manager.agent_capabilities(client_session.agent_id).await;
+        if let Err(unsupported) = capabilities.check(&[AgentCapability::Terminal]) {
+            return unsupported.into_response();
+        }
+    }
+    let user_id = request.user_id.clone();
     match app_state.device_manager.terminal_manager.create_session(client_session_id, request).await {
-        Ok(session) => Json(TerminalSessionInfo {
-            session_id: session.session_id,
-            shell_type: session.shell_type,
-            current_directory: session.current_directory,
-            is_elevated: session.is_elevated,
-            started_at: session.started_at,
-            last_activity: session.last_activity,
-            status: session.status,
-        }).into_response(),
+        Ok(session) => {
+            let mut entry = AuditLog::new("terminal", "terminal_opened")
+                .actor(user_id)
+                .session(client_session_id)
+                .request(&context)
+                .details(serde_json::json!({
+                    "terminal_session_id": session.session_id,
+                    "shell_type": session.shell_type,
+                    "elevated": session.is_elevated,
+                }));
+            if let Some(client_session) = app_state.device_manager.get_session(client_session_id).await {
+                entry = entry.agent(client_session.agent_id);
+            }
+            app_state.device_manager.record_audit(entry).await;
+            Json(TerminalSessionInfo {
+                session_id: session.session_id,
+                shell_type: session.shell_type,
+                current_directory: session.current_directory,
+                is_elevated: session.is_elevated,
+                started_at: session.started_at,
+                last_activity: session.last_activity,
+                status: session.status,
+            }).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -769,10 +813,11 @@ pub async fn websocket_terminal_handler(
     ws: WebSocketUpgrade,
     State(app_state): State<AppState>,
     Path(session_id): Path<Uuid>,
+    context: RequestContext,
 ) -> Response {
-    let terminal_manager = app_state.device_manager.terminal_manager.clone();
+    let device_manager = app_state.device_manager.clone();
     ws.on_upgrade(move |socket| {
-        handle_terminal_websocket(socket, session_id, terminal_manager)
+        handle_terminal_websocket(socket, session_id, device_manager, context)
     })
 }
 
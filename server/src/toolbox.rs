@@ -13,7 +13,9 @@ use tokio::fs;
 use uuid::Uuid;
 use tracing::{info, debug};
 
-use crate::AppState;
+use crate::audit::RequestContext;
+use crate::organizations::Tenant;
+use crate::{models::AuditLog, AppState};
 
 /// ScreenConnect-style toolbox manager for custom tools and scripts
 pub struct ToolboxManager {
@@ -44,6 +46,16 @@ pub struct Tool {
     pub file_size: u64,
     pub checksum: String,
     pub tags: Vec<String>,
+    /// Organization the tool was added for; built-in tools are shared
+    #[serde(default)]
+    pub organization_id: Option<Uuid>,
+}
+
+impl Tool {
+    /// Whether users in `tenant` see and run this tool
+    pub fn visible_to(&self, tenant: Tenant) -> bool {
+        self.organization_id.is_none() || tenant.includes(self.organization_id)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -299,6 +311,7 @@ impl ToolboxManager {
             file_size: 0,
             checksum: String::new(),
             tags: vec!["nirsoft".to_string(), "utility".to_string()],
+            organization_id: None,
         }
     }
     
@@ -330,6 +343,7 @@ impl ToolboxManager {
             file_size: 0,
             checksum: String::new(),
             tags: vec!["sysinternals".to_string(), "microsoft".to_string(), "system".to_string()],
+            organization_id: None,
         }
     }
     
@@ -361,6 +375,7 @@ impl ToolboxManager {
             file_size: 0,
             checksum: String::new(),
             tags: vec!["system".to_string(), "builtin".to_string()],
+            organization_id: None,
         }
     }
     
@@ -392,18 +407,51 @@ impl ToolboxManager {
             file_size: 0,
             checksum: String::new(),
             tags: vec!["network".to_string(), "diagnostic".to_string()],
+            organization_id: None,
         }
     }
     
-    /// Get all available tools
-    pub async fn get_all_tools(&self) -> HashMap<String, Vec<Tool>> {
-        self.tools.read().await.clone()
+    /// Tools users in `tenant` see, by category
+    pub async fn get_all_tools(&self, tenant: Tenant) -> HashMap<String, Vec<Tool>> {
+        self.tools.read().await
+            .iter()
+            .map(|(category, tools)| {
+                let visible: Vec<Tool> = tools.iter().filter(|tool| tool.visible_to(tenant)).cloned().collect();
+                (category.clone(), visible)
+            })
+            .filter(|(_, tools)| !tools.is_empty())
+            .collect()
     }
     
-    /// Get tools by category
-    pub async fn get_tools_by_category(&self, category: &str) -> Vec<Tool> {
+    /// Find a tool by id
+    pub async fn get_tool(&self, tool_id: Uuid) -> Option<Tool> {
         let tools = self.tools.read().await;
-        tools.get(category).cloned().unwrap_or_default()
+        tools.values()
+            .flat_map(|category_tools| category_tools.iter())
+            .find(|t| t.id == tool_id)
+            .cloned()
+    }
+    
+    /// Read a tool's file, preferring an uploaded copy over the shared tools directory
+    pub async fn read_tool_file(&self, tool_id: Uuid) -> Result<(Tool, Vec<u8>), String> {
+        let tool = self.get_tool(tool_id).await
+            .ok_or_else(|| format!("Tool {} not found", tool_id))?;
+        
+        for candidate in [self.storage_path.join("custom").join(&tool.file_path), self.storage_path.join(&tool.file_path)] {
+            if let Ok(data) = fs::read(&candidate).await {
+                return Ok((tool, data));
+            }
+        }
+        
+        Err(format!("File for tool {} is not available on this server", tool.name))
+    }
+    
+    /// Tools of a category users in `tenant` see
+    pub async fn get_tools_by_category(&self, tenant: Tenant, category: &str) -> Vec<Tool> {
+        let tools = self.tools.read().await;
+        tools.get(category)
+            .map(|tools| tools.iter().filter(|tool| tool.visible_to(tenant)).cloned().collect())
+            .unwrap_or_default()
     }
     
     /// Add custom tool
@@ -498,29 +546,51 @@ impl ToolboxManager {
 
 /// API Handlers
 
-/// Get all available tools
+/// Get all tools available to the caller's organization
 pub async fn api_get_tools(
     State(app_state): State<AppState>,
+    tenant: Tenant,
 ) -> impl IntoResponse {
-    let tools = app_state.device_manager.toolbox_manager.get_all_tools().await;
+    let tools = app_state.device_manager.toolbox_manager.get_all_tools(tenant).await;
     Json(tools)
 }
 
 /// Get tools by category
 pub async fn api_get_tools_by_category(
     State(app_state): State<AppState>,
+    tenant: Tenant,
     Path(category): Path<String>,
 ) -> impl IntoResponse {
-    let tools = app_state.device_manager.toolbox_manager.get_tools_by_category(&category).await;
+    let tools = app_state.device_manager.toolbox_manager.get_tools_by_category(tenant, &category).await;
     Json(tools)
 }
 
+/// Whether `device_id` names a device of `tenant`
+async fn device_in_tenant(app_state: &AppState, tenant: Tenant, device_id: &str) -> bool {
+    let Ok(agent_id) = Uuid::parse_str(device_id) else { return false };
+    app_state.device_manager.get_agent(agent_id).await
+        .is_some_and(|(agent, _)| tenant.includes(agent.organization_id))
+}
+
 /// Execute tool
 pub async fn api_execute_tool(
     State(app_state): State<AppState>,
+    tenant: Tenant,
     Path(tool_id): Path<Uuid>,
+    context: RequestContext,
     Json(request): Json<ToolExecutionRequest>,
 ) -> Response {
+    let toolbox = &app_state.device_manager.toolbox_manager;
+    if !toolbox.get_tool(tool_id).await.is_some_and(|tool| tool.visible_to(tenant)) {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": format!("Tool {} not found", tool_id)
+        }))).into_response();
+    }
+    if !device_in_tenant(&app_state, tenant, &request.device_id).await {
+        return (StatusCode::NOT_FOUND, Json(serde_json::json!({
+            "error": format!("Device not found: {}", request.device_id)
+        }))).into_response();
+    }
     match app_state.device_manager.toolbox_manager.execute_tool(
         tool_id,
         request.session_id,
@@ -528,7 +598,25 @@ pub async fn api_execute_tool(
         request.device_id,
         request.parameters,
     ).await {
-        Ok(execution) => Json(execution).into_response(),
+        Ok(execution) => {
+            let mut entry = AuditLog::new("toolbox", "tool_executed")
+                .actor(execution.user_id.clone())
+                .session(execution.session_id)
+                .request(&context)
+                .details(serde_json::json!({
+                    "execution_id": execution.id,
+                    "tool_id": execution.tool_id,
+                    "device_id": execution.device_id,
+                    "parameters": execution.parameters,
+                    "status": execution.status,
+                    "exit_code": execution.exit_code,
+                }));
+            if let Ok(agent_id) = Uuid::parse_str(&execution.device_id) {
+                entry = entry.agent(agent_id);
+            }
+            app_state.device_manager.record_audit(entry).await;
+            Json(execution).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -554,15 +642,24 @@ pub async fn api_upload_tool(
     ).into_response()
 }
 
-/// Get execution history
+/// Get execution history on the caller's organization's devices
 pub async fn api_get_execution_history(
     State(app_state): State<AppState>,
+    tenant: Tenant,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     let limit = params.get("limit")
         .and_then(|s| s.parse::<usize>().ok());
     
-    let history = app_state.device_manager.toolbox_manager.get_execution_history(limit).await;
+    let mut history = Vec::new();
+    for execution in app_state.device_manager.toolbox_manager.get_execution_history(None).await {
+        if limit.is_some_and(|limit| history.len() >= limit) {
+            break;
+        }
+        if tenant.is_super_admin() || device_in_tenant(&app_state, tenant, &execution.device_id).await {
+            history.push(execution);
+        }
+    }
     Json(history)
 }
 
@@ -577,11 +674,40 @@ pub struct ToolExecutionRequest {
 /// Get available tools
 pub async fn api_get_available_tools(
     State(app_state): State<AppState>,
+    tenant: Tenant,
 ) -> impl IntoResponse {
-    let tools = app_state.device_manager.toolbox_manager.get_all_tools().await;
+    let tools = app_state.device_manager.toolbox_manager.get_all_tools(tenant).await;
     Json(tools)
 }
 
+/// Download a tool's file for agents installing server-managed tools
+pub async fn api_download_tool(
+    State(app_state): State<AppState>,
+    tenant: Tenant,
+    Path(tool_id): Path<Uuid>,
+) -> Response {
+    let file = app_state.device_manager.toolbox_manager.read_tool_file(tool_id).await
+        .and_then(|(tool, data)| match tool.visible_to(tenant) {
+            true => Ok((tool, data)),
+            false => Err(format!("Tool {} not found", tool_id)),
+        });
+    match file {
+        Ok((tool, data)) => {
+            debug!("Serving tool {} ({} bytes)", tool.name, data.len());
+            (
+                [(axum::http::header::CONTENT_TYPE, "application/octet-stream")],
+                data,
+            ).into_response()
+        }
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": e
+            }))
+        ).into_response(),
+    }
+}
+
 /// Upload custom tool (stub)
 pub async fn api_upload_custom_tool(
     State(_app_state): State<AppState>,
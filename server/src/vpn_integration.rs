@@ -1,19 +1,47 @@
+//! Tailscale and WireGuard integration
+//!
+//! The server watches its own `tailscale status` and reports the tailnet's
+//! peers; agents report the tailnet they are on when they register, so the
+//! rendezvous can point a viewer in the same tailnet at the device's tailnet
+//! address. For WireGuard the server hands out peer configs, each with a
+//! keypair generated here, for devices to join the relay's subnet.
+
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
+use crate::audit::RequestContext;
+use crate::auth::jwt::{error, AuthUser};
+use crate::models::AuditLog;
+use crate::organizations::Tenant;
 use crate::AppState;
 
+/// How often Tailscale and WireGuard status is refreshed
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long `tailscale status` may take before Tailscale counts as unavailable
+const TAILSCALE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keepalive in device configs, so the device's NAT keeps the tunnel open
+const WIREGUARD_KEEPALIVE: u16 = 25;
+
 /// VPN integration manager for Tailscale and WireGuard
 pub struct VpnManager {
     /// VPN configuration
@@ -22,6 +50,17 @@ pub struct VpnManager {
     peers: Arc<RwLock<HashMap<String, VpnPeer>>>,
     /// VPN status
     status: Arc<RwLock<VpnStatus>>,
+    /// Set once the status refresh task runs
+    monitoring: AtomicBool,
+}
+
+/// A node's address in a tailnet, as its `tailscale status` reports it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TailnetAddress {
+    /// MagicDNS suffix of the tailnet, e.g. `tail1234.ts.net`, which tells
+    /// tailnets apart
+    pub tailnet: String,
+    pub address: IpAddr,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,9 +110,14 @@ pub struct TailscaleServeConfig {
 pub struct WireGuardConfig {
     pub enabled: bool,
     pub interface_name: String,
+    /// Generated with the first peer config when empty
     pub private_key: String,
     pub public_key: String,
     pub listen_port: u16,
+    /// `host:port` devices reach the interface at; the host the peer config
+    /// was requested from, on `listen_port`, when unset
+    #[serde(default)]
+    pub endpoint: Option<String>,
     pub address: String,
     pub dns: Vec<String>,
     pub peers: Vec<WireGuardPeer>,
@@ -141,11 +185,17 @@ pub struct VpnPeer {
     pub tx_bytes: u64,
     pub latency_ms: Option<f64>,
     pub version: Option<String>,
+    /// Agent at this address, when it is a GhostLink device
+    #[serde(default)]
+    pub agent_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VpnStatus {
     pub tailscale_status: Option<TailscaleStatus>,
+    /// Why `tailscale_status` is unset, e.g. Tailscale is not installed
+    #[serde(default)]
+    pub tailscale_error: Option<String>,
     pub wireguard_status: Option<WireGuardStatus>,
     pub vpn_ip: Option<IpAddr>,
     pub public_ip: Option<IpAddr>,
@@ -167,7 +217,19 @@ pub struct TailscaleStatus {
     pub cert_domains: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl TailscaleStatus {
+    /// This node's address in its tailnet, once it is logged in
+    pub fn membership(&self) -> Option<TailnetAddress> {
+        if !self.logged_in || self.magic_dns_suffix.is_empty() {
+            return None;
+        }
+        let addresses: Vec<IpAddr> = self.self_node.addresses.iter().filter_map(|a| a.parse().ok()).collect();
+        let address = addresses.iter().find(|a| a.is_ipv4()).or(addresses.first())?;
+        Some(TailnetAddress { tailnet: self.magic_dns_suffix.clone(), address: *address })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TailscaleNode {
     pub id: String,
     pub name: String,
@@ -213,6 +275,7 @@ impl VpnManager {
             peers: Arc::new(RwLock::new(HashMap::new())),
             status: Arc::new(RwLock::new(VpnStatus {
                 tailscale_status: None,
+                tailscale_error: None,
                 wireguard_status: None,
                 vpn_ip: None,
                 public_ip: None,
@@ -222,6 +285,7 @@ impl VpnManager {
                 uptime_seconds: 0,
                 last_updated: chrono::Utc::now(),
             })),
+            monitoring: AtomicBool::new(false),
         }
     }
     
@@ -257,6 +321,7 @@ impl VpnManager {
                 private_key: "".to_string(),
                 public_key: "".to_string(),
                 listen_port: 51820,
+                endpoint: None,
                 address: "10.0.0.1/24".to_string(),
                 dns: vec!["1.1.1.1".to_string(), "8.8.8.8".to_string()],
                 peers: vec![],
@@ -295,6 +360,9 @@ impl VpnManager {
     pub async fn initialize(&self) -> Result<(), String> {
         info!("Initializing VPN manager");
         
+        // Tailscale is detected whether or not the integration manages it
+        self.start_status_monitoring().await;
+        
         let config = self.config.read().await.clone();
         
         if !config.enabled {
@@ -316,9 +384,6 @@ impl VpnManager {
             }
         }
         
-        // Start status monitoring
-        self.start_status_monitoring().await;
-        
         info!("VPN manager initialized successfully");
         Ok(())
     }
@@ -558,89 +623,62 @@ impl VpnManager {
         Ok(())
     }
     
-    /// Start status monitoring
+    /// Start status monitoring, once
     async fn start_status_monitoring(&self) {
+        if self.monitoring.swap(true, Ordering::SeqCst) {
+            return;
+        }
         let config = self.config.clone();
         let status = self.status.clone();
         let peers = self.peers.clone();
         
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+            let mut interval = tokio::time::interval(STATUS_INTERVAL);
             
             loop {
                 interval.tick().await;
                 
-                let config_read = config.read().await;
-                if !config_read.enabled {
-                    continue;
-                }
-                
-                // Update Tailscale status
-                if let Some(ts_config) = &config_read.tailscale_config {
-                    if ts_config.enabled {
-                        if let Ok(ts_status) = Self::get_tailscale_status().await {
-                            let mut status_write = status.write().await;
-                            status_write.tailscale_status = Some(ts_status);
-                            status_write.last_updated = chrono::Utc::now();
-                        }
-                    }
-                }
+                Self::refresh_tailscale(&status, &peers).await;
                 
                 // Update WireGuard status
-                if let Some(wg_config) = &config_read.wireguard_config {
-                    if wg_config.enabled {
-                        if let Ok(wg_status) = Self::get_wireguard_status(&wg_config.interface_name).await {
-                            let mut status_write = status.write().await;
-                            status_write.wireguard_status = Some(wg_status);
-                            status_write.last_updated = chrono::Utc::now();
-                        }
+                let wg_config = config.read().await.wireguard_config.clone()
+                    .filter(|wg_config| wg_config.enabled);
+                if let Some(wg_config) = wg_config {
+                    if let Ok(wg_status) = Self::get_wireguard_status(&wg_config.interface_name).await {
+                        let mut status_write = status.write().await;
+                        status_write.wireguard_status = Some(wg_status);
+                        status_write.last_updated = chrono::Utc::now();
                     }
                 }
-                
-                // Update peer information
-                Self::update_peer_information(&peers).await;
             }
         });
     }
     
-    /// Get Tailscale status
-    async fn get_tailscale_status() -> Result<TailscaleStatus, String> {
-        let output = Command::new("tailscale")
-            .args(&["status", "--json"])
-            .output()
-            .map_err(|e| format!("Failed to get Tailscale status: {}", e))?;
+    /// Refresh Tailscale status and the peers seen through it
+    async fn refresh_tailscale(status: &RwLock<VpnStatus>, peers: &RwLock<HashMap<String, VpnPeer>>) {
+        let now = Utc::now();
+        let result = query_tailscale().await.and_then(|json| parse_tailscale_status(&json, now));
         
-        if !output.status.success() {
-            return Err("Tailscale status command failed".to_string());
-        }
-        
-        let status_json: serde_json::Value = serde_json::from_slice(&output.stdout)
-            .map_err(|e| format!("Failed to parse Tailscale status: {}", e))?;
-        
-        // Parse Tailscale status (simplified)
-        Ok(TailscaleStatus {
-            version: status_json["Version"].as_str().unwrap_or("unknown").to_string(),
-            logged_in: status_json["BackendState"].as_str() == Some("Running"),
-            tailnet: status_json["CurrentTailnet"]["Name"].as_str().unwrap_or("unknown").to_string(),
-            self_node: TailscaleNode {
-                id: status_json["Self"]["ID"].as_str().unwrap_or("unknown").to_string(),
-                name: status_json["Self"]["DNSName"].as_str().unwrap_or("unknown").to_string(),
-                dns_name: status_json["Self"]["DNSName"].as_str().unwrap_or("unknown").to_string(),
-                addresses: vec![],
-                endpoints: vec![],
-                relay: None,
-                rx_bytes: 0,
-                tx_bytes: 0,
-                created: "".to_string(),
-                last_seen: "".to_string(),
-                expired: false,
-                key_expiry: "".to_string(),
-                machine_status: "active".to_string(),
-            },
-            health_messages: vec![],
-            magic_dns_suffix: ".ts.net".to_string(),
-            cert_domains: vec![],
-        })
+        let mut status = status.write().await;
+        let mut peers = peers.write().await;
+        peers.retain(|_, peer| !matches!(peer.vpn_type, VpnType::Tailscale));
+        match result {
+            Ok((tailscale, tailscale_peers)) => {
+                status.vpn_ip = tailscale.membership().map(|membership| membership.address);
+                status.tailscale_status = Some(tailscale);
+                status.tailscale_error = None;
+                peers.extend(tailscale_peers.into_iter().map(|peer| (peer.id.clone(), peer)));
+            }
+            Err(e) => {
+                debug!("Tailscale status unavailable: {}", e);
+                status.tailscale_status = None;
+                status.tailscale_error = Some(e);
+            }
+        }
+        status.connected_peers = peers.values().filter(|peer| peer.connected).count();
+        status.total_rx_bytes = peers.values().map(|peer| peer.rx_bytes).sum();
+        status.total_tx_bytes = peers.values().map(|peer| peer.tx_bytes).sum();
+        status.last_updated = now;
     }
     
     /// Get WireGuard status
@@ -665,11 +703,6 @@ impl VpnManager {
         })
     }
     
-    /// Update peer information
-    async fn update_peer_information(_peers: &Arc<RwLock<HashMap<String, VpnPeer>>>) {
-        // TODO: Implement peer discovery and status updates
-    }
-    
     /// Check if IP is from VPN
     pub async fn is_vpn_ip(&self, ip: &IpAddr) -> bool {
         let config = self.config.read().await;
@@ -685,15 +718,26 @@ impl VpnManager {
         false
     }
     
-    /// Get VPN configuration
+    /// Get VPN configuration, without the WireGuard private key
     pub async fn get_config(&self) -> VpnConfig {
-        self.config.read().await.clone()
+        let mut config = self.config.read().await.clone();
+        if let Some(wg_config) = &mut config.wireguard_config {
+            wg_config.private_key.clear();
+        }
+        config
     }
     
-    /// Update VPN configuration
-    pub async fn update_config(&self, new_config: VpnConfig) -> Result<(), String> {
+    /// Update VPN configuration; an empty WireGuard private key keeps the
+    /// current one
+    pub async fn update_config(&self, mut new_config: VpnConfig) -> Result<(), String> {
         {
             let mut config = self.config.write().await;
+            if let (Some(new_wg), Some(current_wg)) = (&mut new_config.wireguard_config, &config.wireguard_config) {
+                if new_wg.private_key.is_empty() {
+                    new_wg.private_key = current_wg.private_key.clone();
+                    new_wg.public_key = current_wg.public_key.clone();
+                }
+            }
             *config = new_config;
         }
         
@@ -713,6 +757,329 @@ impl VpnManager {
     pub async fn get_peers(&self) -> HashMap<String, VpnPeer> {
         self.peers.read().await.clone()
     }
+    
+    /// The server's own address in its tailnet
+    pub async fn tailnet(&self) -> Option<TailnetAddress> {
+        self.status.read().await.tailscale_status.as_ref().and_then(TailscaleStatus::membership)
+    }
+    
+    /// Add a device to the WireGuard subnet and return its peer entry and
+    /// config file. The device's keypair is generated here; only its public
+    /// key is kept.
+    pub async fn create_wireguard_peer(
+        &self,
+        name: Option<&str>,
+        request_host: Option<&str>,
+    ) -> Result<(WireGuardPeer, String), String> {
+        let (peer, device_config, live_interface) = {
+            let mut config = self.config.write().await;
+            let wg_config = config.wireguard_config.as_mut().ok_or("WireGuard is not configured")?;
+            if wg_config.private_key.is_empty() {
+                let (private_key, public_key) = generate_wireguard_keypair()?;
+                wg_config.private_key = private_key;
+                wg_config.public_key = public_key;
+                info!("Generated WireGuard keypair for {}", wg_config.interface_name);
+            } else if wg_config.public_key.is_empty() {
+                wg_config.public_key = wireguard_public_key(&wg_config.private_key)?;
+            }
+            
+            let (subnet, address) = next_peer_address(wg_config)?;
+            let (private_key, public_key) = generate_wireguard_keypair()?;
+            let name: String = name.unwrap_or_default().chars().filter(|c| !c.is_control()).collect();
+            let name = match name.trim() {
+                "" => format!("device-{}", address),
+                name => name.to_string(),
+            };
+            let peer = WireGuardPeer {
+                name,
+                public_key,
+                endpoint: None,
+                allowed_ips: vec![IpNetwork::from(address).to_string()],
+                keepalive: None,
+                preshared_key: None,
+            };
+            let endpoint = wg_config.endpoint.clone()
+                .or_else(|| request_host.map(|host| format!("{}:{}", host, wg_config.listen_port)));
+            let device_config = build_peer_config(&peer, &private_key, subnet, wg_config, endpoint.as_deref());
+            wg_config.peers.push(peer.clone());
+            let live_interface = wg_config.enabled.then(|| wg_config.interface_name.clone());
+            (peer, device_config, live_interface)
+        };
+        
+        if let Some(interface) = live_interface {
+            let output = Command::new("sudo")
+                .args(["wg", "set", &interface, "peer", &peer.public_key, "allowed-ips", &peer.allowed_ips.join(",")])
+                .output();
+            match output {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => warn!("Failed to add WireGuard peer {}: {}", peer.name, String::from_utf8_lossy(&output.stderr)),
+                Err(e) => warn!("Failed to add WireGuard peer {}: {}", peer.name, e),
+            }
+        }
+        info!("Created WireGuard peer {} at {}", peer.name, peer.allowed_ips.join(", "));
+        Ok((peer, device_config))
+    }
+}
+
+/// Run `tailscale status --json`; Tailscale missing or stopped is an error
+/// saying so
+async fn query_tailscale() -> Result<Vec<u8>, String> {
+    let output = tokio::process::Command::new("tailscale")
+        .args(["status", "--json"])
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(TAILSCALE_TIMEOUT, output).await {
+        Err(_) => Err(format!("tailscale status did not answer within {:?}", TAILSCALE_TIMEOUT)),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => Err("Tailscale is not installed".to_string()),
+        Ok(Err(e)) => Err(format!("Failed to run tailscale: {}", e)),
+        Ok(Ok(output)) if !output.status.success() => Err(format!(
+            "tailscale status failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Ok(Ok(output)) => Ok(output.stdout),
+    }
+}
+
+/// `tailscale status --json`, as far as it is used
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct RawTailscaleStatus {
+    version: String,
+    backend_state: String,
+    #[serde(rename = "Self")]
+    self_node: Option<RawTailscaleNode>,
+    health: Option<Vec<String>>,
+    #[serde(rename = "MagicDNSSuffix")]
+    magic_dns_suffix: String,
+    current_tailnet: Option<RawTailnet>,
+    cert_domains: Option<Vec<String>>,
+    peer: Option<HashMap<String, RawTailscaleNode>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct RawTailnet {
+    name: String,
+    #[serde(rename = "MagicDNSSuffix")]
+    magic_dns_suffix: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct RawTailscaleNode {
+    #[serde(rename = "ID")]
+    id: String,
+    host_name: String,
+    #[serde(rename = "DNSName")]
+    dns_name: String,
+    #[serde(rename = "TailscaleIPs")]
+    tailscale_ips: Option<Vec<String>>,
+    addrs: Option<Vec<String>>,
+    cur_addr: String,
+    relay: String,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    created: String,
+    last_seen: String,
+    key_expiry: String,
+    expired: bool,
+    online: bool,
+    tags: Option<Vec<String>>,
+}
+
+impl RawTailscaleNode {
+    fn into_node(self) -> TailscaleNode {
+        let dns_name = self.dns_name.trim_end_matches('.').to_string();
+        TailscaleNode {
+            id: self.id,
+            name: self.host_name,
+            dns_name,
+            addresses: self.tailscale_ips.unwrap_or_default(),
+            endpoints: self.addrs.unwrap_or_default(),
+            relay: Some(self.relay).filter(|relay| !relay.is_empty()),
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+            created: self.created,
+            last_seen: self.last_seen,
+            expired: self.expired,
+            key_expiry: self.key_expiry,
+            machine_status: if self.online { "online" } else { "offline" }.to_string(),
+        }
+    }
+    
+    /// The peer as reported by the API, unless it has no tailnet address
+    fn into_peer(self, now: DateTime<Utc>) -> Option<VpnPeer> {
+        let addresses: Vec<IpAddr> = self.tailscale_ips.iter().flatten().filter_map(|a| a.parse().ok()).collect();
+        let vpn_ip = *addresses.iter().find(|a| a.is_ipv4()).or(addresses.first())?;
+        let last_seen = if self.online {
+            now
+        } else {
+            self.last_seen.parse().unwrap_or_default()
+        };
+        let dns_name = self.dns_name.trim_end_matches('.');
+        Some(VpnPeer {
+            id: self.id,
+            name: if dns_name.is_empty() { self.host_name.clone() } else { dns_name.to_string() },
+            vpn_type: VpnType::Tailscale,
+            vpn_ip,
+            public_ip: self.cur_addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()),
+            hostname: Some(self.host_name).filter(|name| !name.is_empty()),
+            tags: self.tags.unwrap_or_default(),
+            last_seen,
+            connected: self.online,
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+            latency_ms: None,
+            version: None,
+            agent_id: None,
+        })
+    }
+}
+
+/// Status and peers from the output of `tailscale status --json`
+pub fn parse_tailscale_status(json: &[u8], now: DateTime<Utc>) -> Result<(TailscaleStatus, Vec<VpnPeer>), String> {
+    let raw: RawTailscaleStatus = serde_json::from_slice(json)
+        .map_err(|e| format!("Failed to parse Tailscale status: {}", e))?;
+    let tailnet = raw.current_tailnet.unwrap_or_default();
+    let magic_dns_suffix = if tailnet.magic_dns_suffix.is_empty() { raw.magic_dns_suffix } else { tailnet.magic_dns_suffix };
+    let mut peers: Vec<VpnPeer> = raw.peer.unwrap_or_default()
+        .into_values()
+        .filter_map(|peer| peer.into_peer(now))
+        .collect();
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    let status = TailscaleStatus {
+        version: raw.version,
+        logged_in: raw.backend_state == "Running",
+        tailnet: tailnet.name,
+        self_node: raw.self_node.map(RawTailscaleNode::into_node).unwrap_or_default(),
+        health_messages: raw.health.unwrap_or_default(),
+        magic_dns_suffix,
+        cert_domains: raw.cert_domains.unwrap_or_default(),
+    };
+    Ok((status, peers))
+}
+
+/// Mark peers that are GhostLink agents, and list agents on tailnets the
+/// server is not part of as peers of their own
+pub fn merge_agent_peers(
+    peers: &mut HashMap<String, VpnPeer>,
+    agents: Vec<(Uuid, TailnetAddress, DateTime<Utc>)>,
+    server_tailnet: Option<&str>,
+) {
+    for (agent_id, membership, last_seen) in agents {
+        if server_tailnet == Some(membership.tailnet.as_str()) {
+            if let Some(peer) = peers.values_mut().find(|peer| peer.vpn_ip == membership.address) {
+                peer.agent_id = Some(agent_id);
+                continue;
+            }
+        }
+        peers.insert(agent_id.to_string(), VpnPeer {
+            id: agent_id.to_string(),
+            name: membership.tailnet.clone(),
+            vpn_type: VpnType::Tailscale,
+            vpn_ip: membership.address,
+            public_ip: None,
+            hostname: None,
+            tags: Vec::new(),
+            last_seen,
+            // Registered agents are online
+            connected: true,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            latency_ms: None,
+            version: None,
+            agent_id: Some(agent_id),
+        });
+    }
+}
+
+/// A WireGuard keypair, base64 like `wg genkey` and `wg pubkey` print them
+pub fn generate_wireguard_keypair() -> Result<(String, String), String> {
+    let mut private_key = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut private_key)
+        .map_err(|_| "Failed to generate a WireGuard key".to_string())?;
+    // Clamped the way `wg genkey` does
+    private_key[0] &= 248;
+    private_key[31] = (private_key[31] & 127) | 64;
+    let private_key = BASE64.encode(private_key);
+    let public_key = wireguard_public_key(&private_key)?;
+    Ok((private_key, public_key))
+}
+
+/// Public key of a base64 WireGuard private key
+pub fn wireguard_public_key(private_key: &str) -> Result<String, String> {
+    let bytes: [u8; 32] = BASE64.decode(private_key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("WireGuard private key must be 32 bytes of base64")?;
+    let secret = x25519_dalek::StaticSecret::from(bytes);
+    Ok(BASE64.encode(x25519_dalek::PublicKey::from(&secret).as_bytes()))
+}
+
+/// The interface's subnet and its first address neither the interface nor a
+/// peer uses
+fn next_peer_address(config: &WireGuardConfig) -> Result<(IpNetwork, IpAddr), String> {
+    let interface: IpNetwork = config.address.parse()
+        .map_err(|e| format!("Invalid WireGuard address {}: {}", config.address, e))?;
+    let subnet = IpNetwork::new(interface.network(), interface.prefix()).map_err(|e| e.to_string())?;
+    let used: Vec<IpAddr> = config.peers.iter()
+        .flat_map(|peer| &peer.allowed_ips)
+        .filter_map(|allowed| allowed.parse::<IpNetwork>().ok())
+        .map(|allowed| allowed.ip())
+        .chain([interface.ip()])
+        .collect();
+    let broadcast = match subnet {
+        IpNetwork::V4(subnet) => Some(IpAddr::V4(subnet.broadcast())),
+        IpNetwork::V6(_) => None,
+    };
+    subnet.iter()
+        // The network address itself
+        .skip(1)
+        .filter(|address| Some(*address) != broadcast)
+        .find(|address| !used.contains(address))
+        .map(|address| (subnet, address))
+        .ok_or_else(|| format!("No free address left in {}", subnet))
+}
+
+/// Config file for a device to join the relay's subnet
+fn build_peer_config(
+    peer: &WireGuardPeer,
+    private_key: &str,
+    subnet: IpNetwork,
+    server: &WireGuardConfig,
+    endpoint: Option<&str>,
+) -> String {
+    let mut content = format!(
+        "# GhostLink WireGuard peer {}\n[Interface]\nPrivateKey = {}\nAddress = {}\n",
+        peer.name,
+        private_key,
+        peer.allowed_ips.join(", ")
+    );
+    if !server.dns.is_empty() {
+        content.push_str(&format!("DNS = {}\n", server.dns.join(", ")));
+    }
+    content.push_str(&format!(
+        "\n[Peer]\n# GhostLink relay\nPublicKey = {}\nAllowedIPs = {}\n",
+        server.public_key,
+        subnet
+    ));
+    if let Some(endpoint) = endpoint {
+        content.push_str(&format!("Endpoint = {}\n", endpoint));
+    }
+    content.push_str(&format!("PersistentKeepalive = {}\n", WIREGUARD_KEEPALIVE));
+    content
+}
+
+/// Host of the request without its port, for the endpoint of peer configs
+fn request_host(headers: &HeaderMap) -> Option<String> {
+    let host = headers.get(header::HOST)?.to_str().ok()?.trim();
+    let host = match host.strip_prefix('[') {
+        // [IPv6]:port
+        Some(rest) => &host[..rest.find(']')? + 2],
+        None => host.split(':').next()?,
+    };
+    Some(host.to_string()).filter(|host| !host.is_empty())
 }
 
 /// API Handlers
@@ -751,32 +1118,265 @@ pub async fn api_get_vpn_status(
     Json(status)
 }
 
-/// Get VPN peers
+/// Get VPN peers: those of the server's tailnet and WireGuard interface,
+/// and agents reporting a tailnet. Users in an organization only see its
+/// agents.
 pub async fn api_get_vpn_peers(
     State(app_state): State<AppState>,
+    tenant: Tenant,
 ) -> impl IntoResponse {
-    let peers = app_state.device_manager.vpn_manager.get_peers().await;
+    let device_manager = &app_state.device_manager;
+    let vpn_manager = &device_manager.vpn_manager;
+    let mut peers = vpn_manager.get_peers().await;
+    let agents = device_manager.rendezvous.tailnet_members(Utc::now()).await;
+    let server_tailnet = vpn_manager.tailnet().await.map(|membership| membership.tailnet);
+    merge_agent_peers(&mut peers, agents, server_tailnet.as_deref());
+    
+    if !tenant.is_super_admin() {
+        let mut visible = HashMap::new();
+        for (id, peer) in peers {
+            let Some(agent_id) = peer.agent_id else { continue };
+            let in_tenant = device_manager.get_agent(agent_id).await
+                .is_some_and(|(agent, _)| tenant.includes(agent.organization_id));
+            if in_tenant {
+                visible.insert(id, peer);
+            }
+        }
+        peers = visible;
+    }
     Json(peers)
 }
 
-/// Enable Tailscale (stub)
+/// Turn on the Tailscale integration, which brings tailscaled up with the
+/// configured settings
 pub async fn api_enable_tailscale(
-    State(_app_state): State<AppState>,
-) -> impl IntoResponse {
-    // TODO: Implement Tailscale integration
-    Json(serde_json::json!({
-        "status": "error",
-        "message": "Tailscale integration not yet implemented"
-    }))
+    State(app_state): State<AppState>,
+) -> Response {
+    let vpn_manager = &app_state.device_manager.vpn_manager;
+    let mut config = vpn_manager.get_config().await;
+    let Some(tailscale_config) = &mut config.tailscale_config else {
+        return error(StatusCode::CONFLICT, "Tailscale is not configured");
+    };
+    tailscale_config.enabled = true;
+    config.enabled = true;
+    match vpn_manager.update_config(config).await {
+        Ok(()) => Json(serde_json::json!({ "status": "enabled" })).into_response(),
+        Err(e) => error(StatusCode::CONFLICT, e),
+    }
 }
 
-/// Get WireGuard config (stub)
+#[derive(Debug, Default, Deserialize)]
+pub struct WireGuardPeerQuery {
+    /// Name of the device, for the config's comments
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Add a device to the WireGuard subnet and download its config file
 pub async fn api_get_wireguard_config(
-    State(_app_state): State<AppState>,
-) -> impl IntoResponse {
-    // TODO: Implement WireGuard config generation
-    Json(serde_json::json!({
-        "status": "error",
-        "message": "WireGuard config generation not yet implemented"
-    }))
-}
\ No newline at end of file
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    context: RequestContext,
+    headers: HeaderMap,
+    Query(query): Query<WireGuardPeerQuery>,
+) -> Response {
+    let vpn_manager = &app_state.device_manager.vpn_manager;
+    let host = request_host(&headers);
+    let (peer, config) = match vpn_manager.create_wireguard_peer(query.name.as_deref(), host.as_deref()).await {
+        Ok(created) => created,
+        Err(e) => return error(StatusCode::CONFLICT, e),
+    };
+    app_state.device_manager.record_audit(
+        AuditLog::new("config", "wireguard_peer_created")
+            .actor(user.user_id.to_string())
+            .request(&context)
+            .details(serde_json::json!({
+                "name": peer.name,
+                "public_key": peer.public_key,
+                "allowed_ips": peer.allowed_ips,
+            })),
+    ).await;
+    
+    let file_name: String = peer.name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect();
+    (
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.conf\"", file_name)),
+        ],
+        config,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = r#"{
+        "Version": "1.62.0-t1234",
+        "BackendState": "Running",
+        "TailscaleIPs": ["100.64.0.1", "fd7a:115c:a1e0::1"],
+        "Self": {
+            "ID": "nSelf", "HostName": "ghostlink", "DNSName": "ghostlink.tail1234.ts.net.",
+            "TailscaleIPs": ["fd7a:115c:a1e0::1", "100.64.0.1"], "Addrs": ["203.0.113.5:41641"],
+            "Relay": "fra", "Online": true, "KeyExpiry": "2026-12-01T00:00:00Z"
+        },
+        "Health": null,
+        "MagicDNSSuffix": "tail1234.ts.net",
+        "CurrentTailnet": {"Name": "example.com", "MagicDNSSuffix": "tail1234.ts.net", "MagicDNSEnabled": true},
+        "CertDomains": ["ghostlink.tail1234.ts.net"],
+        "Peer": {
+            "nodekey:aa": {
+                "ID": "nTill", "HostName": "till-01", "DNSName": "till-01.tail1234.ts.net.",
+                "TailscaleIPs": ["100.64.0.7"], "CurAddr": "198.51.100.20:41641",
+                "RxBytes": 1200, "TxBytes": 800, "Online": true, "Tags": ["tag:pos"]
+            },
+            "nodekey:bb": {
+                "ID": "nLaptop", "HostName": "laptop", "DNSName": "laptop.tail1234.ts.net.",
+                "TailscaleIPs": ["100.64.0.9"], "Online": false, "LastSeen": "2026-10-01T08:00:00Z"
+            },
+            "nodekey:cc": {"ID": "nNoAddress", "HostName": "pending"}
+        }
+    }"#;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn wireguard() -> WireGuardConfig {
+        VpnManager::default_config().wireguard_config.unwrap()
+    }
+
+    #[test]
+    fn test_parse_tailscale_status() {
+        let now = Utc::now();
+        let (status, peers) = parse_tailscale_status(STATUS.as_bytes(), now).unwrap();
+        assert!(status.logged_in);
+        assert_eq!(status.tailnet, "example.com");
+        assert_eq!(status.self_node.dns_name, "ghostlink.tail1234.ts.net");
+        assert_eq!(status.self_node.relay.as_deref(), Some("fra"));
+        // IPv4 is preferred for the node's own address
+        assert_eq!(
+            status.membership(),
+            Some(TailnetAddress { tailnet: "tail1234.ts.net".to_string(), address: "100.64.0.1".parse().unwrap() })
+        );
+
+        // Peers without an address are left out
+        assert_eq!(peers.len(), 2);
+        let (laptop, till) = (&peers[0], &peers[1]);
+        assert_eq!(till.name, "till-01.tail1234.ts.net");
+        assert_eq!(till.vpn_ip, "100.64.0.7".parse::<IpAddr>().unwrap());
+        assert_eq!(till.public_ip, Some("198.51.100.20".parse().unwrap()));
+        assert!(till.connected && till.last_seen == now);
+        assert_eq!(till.tags, ["tag:pos"]);
+        assert!(!laptop.connected);
+        assert_eq!(laptop.last_seen, "2026-10-01T08:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    }
+
+    #[test]
+    fn test_logged_out_tailscale_has_no_membership() {
+        let json = br#"{"Version": "1.62.0", "BackendState": "NeedsLogin", "Self": {"ID": "n1"}, "Peer": null}"#;
+        let (status, peers) = parse_tailscale_status(json, Utc::now()).unwrap();
+        assert!(!status.logged_in);
+        assert_eq!(status.membership(), None);
+        assert!(peers.is_empty());
+        assert!(parse_tailscale_status(b"failed to connect to local tailscaled", Utc::now()).is_err());
+    }
+
+    #[test]
+    fn test_agents_are_matched_to_tailnet_peers() {
+        let now = Utc::now();
+        let (_, peers) = parse_tailscale_status(STATUS.as_bytes(), now).unwrap();
+        let mut peers: HashMap<String, VpnPeer> = peers.into_iter().map(|peer| (peer.id.clone(), peer)).collect();
+        let (till, elsewhere) = (Uuid::new_v4(), Uuid::new_v4());
+        let agents = vec![
+            (till, TailnetAddress { tailnet: "tail1234.ts.net".to_string(), address: "100.64.0.7".parse().unwrap() }, now),
+            (elsewhere, TailnetAddress { tailnet: "tail9999.ts.net".to_string(), address: "100.64.0.7".parse().unwrap() }, now),
+        ];
+        merge_agent_peers(&mut peers, agents, Some("tail1234.ts.net"));
+
+        assert_eq!(peers["nTill"].agent_id, Some(till));
+        assert_eq!(peers["nLaptop"].agent_id, None);
+        // The same address in another tailnet is another machine
+        let other = &peers[&elsewhere.to_string()];
+        assert_eq!((other.agent_id, other.name.as_str()), (Some(elsewhere), "tail9999.ts.net"));
+        assert!(other.connected);
+    }
+
+    #[test]
+    fn test_wireguard_keys() {
+        // RFC 7748, section 6.1
+        let private_key = BASE64.encode(unhex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"));
+        let public_key = unhex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        assert_eq!(wireguard_public_key(&private_key).unwrap(), BASE64.encode(public_key));
+        assert!(wireguard_public_key("c2hvcnQ=").is_err());
+
+        let (private_key, public_key) = generate_wireguard_keypair().unwrap();
+        let bytes = BASE64.decode(&private_key).unwrap();
+        assert_eq!((bytes[0] & 7, bytes[31] & 0xc0), (0, 0x40), "clamped like wg genkey");
+        assert_eq!(wireguard_public_key(&private_key).unwrap(), public_key);
+    }
+
+    #[test]
+    fn test_peer_addresses_skip_used_ones() {
+        let mut config = wireguard();
+        config.address = "10.8.0.1/30".to_string();
+        let (subnet, first) = next_peer_address(&config).unwrap();
+        assert_eq!((subnet.to_string(), first.to_string()), ("10.8.0.0/30".to_string(), "10.8.0.2".to_string()));
+
+        config.peers.push(WireGuardPeer {
+            name: "till-01".to_string(),
+            public_key: String::new(),
+            endpoint: None,
+            allowed_ips: vec!["10.8.0.2/32".to_string()],
+            keepalive: None,
+            preshared_key: None,
+        });
+        // 10.8.0.3 is the broadcast address
+        assert!(next_peer_address(&config).unwrap_err().contains("No free address"));
+    }
+
+    #[tokio::test]
+    async fn test_wireguard_peer_config() {
+        let manager = VpnManager::new();
+        let (peer, config) = manager.create_wireguard_peer(Some("till-01\n[Peer]"), Some("relay.example.com")).await.unwrap();
+        assert_eq!(peer.name, "till-01[Peer]");
+        assert_eq!(peer.allowed_ips, ["10.0.0.2/32"]);
+
+        let server = manager.config.read().await.wireguard_config.clone().unwrap();
+        assert_eq!(wireguard_public_key(&server.private_key).unwrap(), server.public_key);
+        assert_eq!(server.peers.len(), 1);
+        assert!(config.contains("Address = 10.0.0.2/32\n"));
+        assert!(config.contains(&format!("PublicKey = {}\n", server.public_key)));
+        assert!(config.contains("AllowedIPs = 10.0.0.0/24\n"));
+        assert!(config.contains("Endpoint = relay.example.com:51820\n"));
+        // The device's private key is handed out, not kept
+        let device_key = config.lines().find_map(|line| line.strip_prefix("PrivateKey = ")).unwrap();
+        assert_eq!(wireguard_public_key(device_key).unwrap(), peer.public_key);
+        assert_eq!(config.lines().filter(|line| *line == "[Peer]").count(), 1);
+
+        let (second, _) = manager.create_wireguard_peer(None, None).await.unwrap();
+        assert_eq!((second.name.as_str(), second.allowed_ips[0].as_str()), ("device-10.0.0.3", "10.0.0.3/32"));
+
+        // The server's private key stays out of the config API, and saving
+        // that config keeps it
+        let shown = manager.get_config().await;
+        assert!(shown.wireguard_config.as_ref().unwrap().private_key.is_empty());
+        manager.update_config(shown).await.unwrap();
+        assert_eq!(manager.config.read().await.wireguard_config.as_ref().unwrap().private_key, server.private_key);
+    }
+
+    #[test]
+    fn test_request_host() {
+        let host = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, value.parse().unwrap());
+            request_host(&headers)
+        };
+        assert_eq!(host("relay.example.com:8443").as_deref(), Some("relay.example.com"));
+        assert_eq!(host("[2001:db8::1]:8443").as_deref(), Some("[2001:db8::1]"));
+        assert_eq!(request_host(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,380 @@
+//! Wake-on-LAN through a proxy agent
+//!
+//! Magic packets don't cross routers, so the server can't wake a device
+//! itself. It picks an online agent that shares a subnet with the sleeping
+//! device and has that agent broadcast the packet there. Agents report their
+//! adapters when they register; once a device is asleep, those last known
+//! MACs and subnets are all the server has to go on. An IPv6 prefix shared
+//! with the device counts as a subnet too; on those the proxy sends the
+//! packet to the all-nodes group `ff02::1`.
+
+use axum::{
+    extract::{ws::Message, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::{authz, jwt::{error, AuthUser}};
+use crate::device_manager::DeviceManager;
+use crate::models::{Agent, AuditLog, NetworkInterface};
+use crate::AppState;
+
+/// How long the API waits for a woken device when the caller doesn't say
+pub const DEFAULT_WAKE_WAIT_SECS: u64 = 60;
+
+/// Longest a caller may ask the API to wait
+pub const MAX_WAKE_WAIT_SECS: u64 = 300;
+
+/// How often to check whether the device came online
+const WAKE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A magic packet for the proxy to send
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WakePacket {
+    pub mac: String,
+    /// Directed broadcast address of the IPv4 subnet the proxy shares with
+    /// the device; unset when they share only an IPv6 prefix
+    pub broadcast: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WakePlan {
+    pub proxy: Uuid,
+    pub packets: Vec<WakePacket>,
+}
+
+#[derive(Debug)]
+pub enum WakeError {
+    NotFound,
+    AlreadyOnline,
+    /// The device never reported an adapter that can be woken
+    NoMacAddress,
+    /// The chosen proxy went away before it got the packets
+    ProxyUnavailable(String),
+}
+
+impl IntoResponse for WakeError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            WakeError::NotFound => (StatusCode::NOT_FOUND, "Device not found".to_string()),
+            WakeError::AlreadyOnline => (StatusCode::CONFLICT, "Device is already online".to_string()),
+            WakeError::NoMacAddress => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "No MAC address with a known subnet has been reported for this device".to_string(),
+            ),
+            WakeError::ProxyUnavailable(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("Proxy agent unavailable: {}", e)),
+        };
+        error(status, message)
+    }
+}
+
+/// Result of a wake request
+#[derive(Debug, Serialize)]
+pub struct WakeOutcome {
+    pub proxy_found: bool,
+    pub proxy_agent_id: Option<Uuid>,
+    pub packets: Vec<WakePacket>,
+    /// Whether the device came online within the wait
+    pub online: bool,
+}
+
+/// Lowercase `aa:bb:cc:dd:ee:ff`, or `None` for anything that can't be
+/// woken, such as the all-zero addresses of virtual adapters
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let digits: String = mac.chars().filter(|c| !matches!(c, ':' | '-' | '.')).collect();
+    if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes: Vec<u8> = (0..12).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap()).collect();
+    // Group addresses, broadcast included, never belong to a single NIC
+    if bytes.iter().all(|&byte| byte == 0) || bytes[0] & 1 == 1 {
+        return None;
+    }
+    Some(bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":"))
+}
+
+/// Subnets an adapter is on that a broadcast or multicast can reach
+fn subnets(interface: &NetworkInterface) -> Vec<IpNetwork> {
+    let ipv4 = interface
+        .ipv4
+        .iter()
+        .filter_map(|cidr| cidr.parse::<Ipv4Network>().ok())
+        // Point-to-point links and VPN host routes have no broadcast domain
+        .filter(|network| !network.ip().is_loopback() && network.prefix() < 31)
+        .map(IpNetwork::V4);
+    let ipv6 = interface
+        .ipv6
+        .iter()
+        .filter_map(|cidr| cidr.parse::<Ipv6Network>().ok())
+        // Every link has fe80::/64, so only routable prefixes tell links
+        // apart, and longer ones are host routes
+        .filter(|network| {
+            let ip = network.ip();
+            !ip.is_loopback() && !ip.is_multicast() && ip.segments()[0] & 0xffc0 != 0xfe80 && network.prefix() <= 64
+        })
+        .map(IpNetwork::V6);
+    ipv4.chain(ipv6).collect()
+}
+
+/// Pick the online agent that can reach the most of the target's adapters,
+/// and the packets it should send.
+///
+/// Only agents of the target's organization are considered; ties go to the
+/// lowest agent ID so repeated requests use the same proxy.
+pub fn plan_wake(target: &Agent, candidates: &[Agent]) -> Result<Option<WakePlan>, WakeError> {
+    let adapters: Vec<(String, Vec<IpNetwork>)> = target
+        .network_interfaces()
+        .iter()
+        .filter_map(|interface| Some((normalize_mac(interface.mac.as_deref()?)?, subnets(interface))))
+        .filter(|(_, subnets)| !subnets.is_empty())
+        .collect();
+    if adapters.is_empty() {
+        return Err(WakeError::NoMacAddress);
+    }
+
+    let plan = candidates
+        .iter()
+        .filter(|candidate| candidate.id != target.id && candidate.organization_id == target.organization_id)
+        .filter_map(|candidate| {
+            let addresses: Vec<IpAddr> = candidate
+                .network_interfaces()
+                .iter()
+                .flat_map(subnets)
+                .map(|network| network.ip())
+                .collect();
+
+            let mut packets = Vec::new();
+            for (mac, networks) in &adapters {
+                for network in networks {
+                    let broadcast = match network {
+                        IpNetwork::V4(network) => Some(network.broadcast()),
+                        IpNetwork::V6(_) => None,
+                    };
+                    let packet = WakePacket { mac: mac.clone(), broadcast };
+                    if addresses.iter().any(|&address| network.contains(address)) && !packets.contains(&packet) {
+                        packets.push(packet);
+                    }
+                }
+            }
+            // The proxy multicasts on its IPv6 links with every packet anyway
+            let broadcast_macs: Vec<String> =
+                packets.iter().filter(|packet| packet.broadcast.is_some()).map(|packet| packet.mac.clone()).collect();
+            packets.retain(|packet| packet.broadcast.is_some() || !broadcast_macs.contains(&packet.mac));
+            (!packets.is_empty()).then_some(WakePlan { proxy: candidate.id, packets })
+        })
+        .max_by(|a, b| a.packets.len().cmp(&b.packets.len()).then(b.proxy.cmp(&a.proxy)));
+    Ok(plan)
+}
+
+/// Have a proxy wake `target_id`, then wait up to `wait` for it to connect
+pub async fn wake_device(device_manager: &DeviceManager, target_id: Uuid, wait: Duration) -> Result<WakeOutcome, WakeError> {
+    let (target, online) = device_manager.get_agent(target_id).await.ok_or(WakeError::NotFound)?;
+    if online {
+        return Err(WakeError::AlreadyOnline);
+    }
+
+    let candidates = device_manager.get_connected_devices().await;
+    let Some(plan) = plan_wake(&target, &candidates)? else {
+        return Ok(WakeOutcome { proxy_found: false, proxy_agent_id: None, packets: Vec::new(), online: false });
+    };
+
+    for packet in &plan.packets {
+        let command = serde_json::json!({
+            "type": "WakeOnLan",
+            "mac": packet.mac,
+            "broadcast": packet.broadcast,
+        });
+        device_manager
+            .send_to_device(plan.proxy, Message::Text(command.to_string()))
+            .await
+            .map_err(WakeError::ProxyUnavailable)?;
+    }
+    info!("Asked agent {} to wake device {} ({} packets)", plan.proxy, target_id, plan.packets.len());
+
+    let online = wait_until_online(device_manager, target_id, wait).await;
+    Ok(WakeOutcome { proxy_found: true, proxy_agent_id: Some(plan.proxy), packets: plan.packets, online })
+}
+
+async fn wait_until_online(device_manager: &DeviceManager, agent_id: Uuid, wait: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        if matches!(device_manager.get_agent(agent_id).await, Some((_, true))) {
+            return true;
+        }
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        tokio::time::sleep(WAKE_POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WakeRequest {
+    /// Seconds to wait for the device to come online; 0 returns right away
+    pub wait_secs: Option<u64>,
+}
+
+/// Wake a sleeping device through an online agent on its subnet
+pub async fn api_wake_device(
+    State(app_state): State<AppState>,
+    user: AuthUser,
+    Path(agent_id): Path<String>,
+    request: Option<Json<WakeRequest>>,
+) -> Response {
+    let Ok(agent_uuid) = Uuid::parse_str(&agent_id) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": "Invalid agent ID format"
+        }))).into_response();
+    };
+    if let Err(e) = authz::check_agent_permission(&app_state, &user, agent_uuid, true).await {
+        return e.into_response();
+    }
+
+    let wait_secs = request
+        .and_then(|Json(request)| request.wait_secs)
+        .unwrap_or(DEFAULT_WAKE_WAIT_SECS)
+        .min(MAX_WAKE_WAIT_SECS);
+    let device_manager = &app_state.device_manager;
+    let outcome = match wake_device(device_manager, agent_uuid, Duration::from_secs(wait_secs)).await {
+        Ok(outcome) => outcome,
+        Err(e) => return e.into_response(),
+    };
+
+    device_manager.record_audit(
+        AuditLog::new("device", "wake_requested")
+            .actor(user.user_id.to_string())
+            .details(serde_json::json!({
+                "agent_id": agent_uuid,
+                "proxy_agent_id": outcome.proxy_agent_id,
+                "online": outcome.online,
+            })),
+    ).await;
+    Json(outcome).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixtures;
+    use crate::device_manager::DeviceRegistration;
+    use crate::relay::outbound;
+
+    fn interface(mac: Option<&str>, addresses: &[&str]) -> NetworkInterface {
+        let (ipv6, ipv4) = addresses.iter().map(|cidr| cidr.to_string()).partition(|cidr| cidr.contains(':'));
+        NetworkInterface { name: "eth0".to_string(), mac: mac.map(str::to_string), ipv4, ipv6 }
+    }
+
+    fn agent(interfaces: Vec<NetworkInterface>) -> Agent {
+        let mut agent = fixtures::agent("desk");
+        agent.connection_info.insert("interfaces".to_string(), serde_json::json!(interfaces));
+        agent
+    }
+
+    #[test]
+    fn test_normalize_mac() {
+        assert_eq!(normalize_mac("AA-BB-CC-DD-EE-F0").as_deref(), Some("aa:bb:cc:dd:ee:f0"));
+        assert_eq!(normalize_mac("aabb.ccdd.eef0").as_deref(), Some("aa:bb:cc:dd:ee:f0"));
+        for invalid in ["", "aa:bb:cc:dd:ee", "zz:bb:cc:dd:ee:ff", "00:00:00:00:00:00", "ff:ff:ff:ff:ff:ff", "01:00:5e:00:00:01"] {
+            assert_eq!(normalize_mac(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_proxy_selection() {
+        // Two NICs, one on each of two office subnets
+        let target = agent(vec![
+            interface(Some("aa:bb:cc:dd:ee:01"), &["192.168.1.20/24"]),
+            interface(Some("aa:bb:cc:dd:ee:02"), &["10.0.5.20/16"]),
+            interface(None, &["172.16.0.20/24"]),
+        ]);
+        let office = agent(vec![interface(Some("aa:bb:cc:dd:ee:10"), &["192.168.1.30/24"])]);
+        let both = agent(vec![
+            interface(Some("aa:bb:cc:dd:ee:11"), &["192.168.1.31/24"]),
+            interface(Some("aa:bb:cc:dd:ee:12"), &["10.0.9.9/16", "127.0.0.1/8"]),
+        ]);
+        let remote = agent(vec![interface(Some("aa:bb:cc:dd:ee:13"), &["192.168.2.30/24", "172.16.0.5/24"])]);
+        let mut other_org = both.clone();
+        other_org.id = Uuid::new_v4();
+        other_org.organization_id = Some(Uuid::new_v4());
+
+        let plan = plan_wake(&target, &[office.clone(), both.clone(), remote.clone(), other_org]).unwrap().unwrap();
+        assert_eq!(plan.proxy, both.id);
+        assert_eq!(plan.packets, vec![
+            WakePacket { mac: "aa:bb:cc:dd:ee:01".to_string(), broadcast: Some(Ipv4Addr::new(192, 168, 1, 255)) },
+            WakePacket { mac: "aa:bb:cc:dd:ee:02".to_string(), broadcast: Some(Ipv4Addr::new(10, 0, 255, 255)) },
+        ]);
+
+        let plan = plan_wake(&target, &[office.clone(), remote.clone()]).unwrap().unwrap();
+        assert_eq!((plan.proxy, plan.packets.len()), (office.id, 1));
+
+        // The adapter without a MAC can't be woken even though remote shares its subnet
+        assert_eq!(plan_wake(&target, &[remote, target.clone()]).unwrap(), None);
+
+        let unknown = agent(vec![interface(None, &["192.168.1.40/24"]), interface(Some("aa:bb:cc:dd:ee:03"), &[])]);
+        assert!(matches!(plan_wake(&unknown, &[office]), Err(WakeError::NoMacAddress)));
+    }
+
+    #[test]
+    fn test_proxy_selection_over_ipv6() {
+        let target = agent(vec![interface(Some("aa:bb:cc:dd:ee:01"), &["2001:db8:1::20/64", "fe80::20/64"])]);
+        let same_link = agent(vec![interface(Some("aa:bb:cc:dd:ee:10"), &["2001:db8:1::30/64", "fe80::30/64"])]);
+        // Link-local prefixes are the same on every link
+        let elsewhere = agent(vec![interface(Some("aa:bb:cc:dd:ee:11"), &["2001:db8:2::30/64", "fe80::31/64"])]);
+
+        let plan = plan_wake(&target, &[elsewhere.clone(), same_link.clone()]).unwrap().unwrap();
+        assert_eq!(plan.proxy, same_link.id);
+        assert_eq!(plan.packets, vec![WakePacket { mac: "aa:bb:cc:dd:ee:01".to_string(), broadcast: None }]);
+        assert_eq!(plan_wake(&target, &[elsewhere]).unwrap(), None);
+
+        // Sharing an IPv4 subnet as well, the broadcast packet covers the link
+        let dual = agent(vec![interface(Some("aa:bb:cc:dd:ee:02"), &["192.168.1.20/24", "2001:db8:1::21/64"])]);
+        let proxy = agent(vec![interface(Some("aa:bb:cc:dd:ee:12"), &["192.168.1.30/24", "2001:db8:1::31/64"])]);
+        let plan = plan_wake(&dual, &[proxy]).unwrap().unwrap();
+        assert_eq!(plan.packets, vec![
+            WakePacket { mac: "aa:bb:cc:dd:ee:02".to_string(), broadcast: Some(Ipv4Addr::new(192, 168, 1, 255)) },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_wake_sends_packets_through_proxy() {
+        let manager = DeviceManager::new();
+        let register = |hostname: &str, mac: &str, ip: &str| DeviceRegistration {
+            name: None,
+            hostname: hostname.to_string(),
+            platform: "windows".to_string(),
+            architecture: "x86_64".to_string(),
+            version: "1.0.0".to_string(),
+            public_key: None,
+            agent_id: None,
+            interfaces: vec![interface(Some(mac), &[ip])],
+            capabilities: Default::default(),
+            organization_id: None,
+        };
+        let target = manager.register_device(register("desk-01", "aa:bb:cc:dd:ee:01", "192.168.1.20/24"), outbound::channel().0).await.unwrap();
+        manager.disconnect_device(target).await;
+
+        let outcome = wake_device(&manager, target, Duration::ZERO).await.unwrap();
+        assert!(!outcome.proxy_found);
+
+        let (tx, rx) = outbound::channel();
+        let proxy = manager.register_device(register("desk-02", "aa:bb:cc:dd:ee:02", "192.168.1.21/24"), tx).await.unwrap();
+        let outcome = wake_device(&manager, target, Duration::ZERO).await.unwrap();
+        assert_eq!((outcome.proxy_found, outcome.proxy_agent_id, outcome.online), (true, Some(proxy), false));
+
+        let Some(Message::Text(text)) = rx.recv().await else { panic!("expected a WakeOnLan command") };
+        let command: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(command["type"], "WakeOnLan");
+        assert_eq!(command["mac"], "aa:bb:cc:dd:ee:01");
+        assert_eq!(command["broadcast"], "192.168.1.255");
+
+        assert!(matches!(wake_device(&manager, proxy, Duration::ZERO).await, Err(WakeError::AlreadyOnline)));
+    }
+}
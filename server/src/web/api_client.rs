@@ -6,6 +6,9 @@ use wasm_bindgen_futures::JsFuture;
 use web_sys::{Request, RequestInit, RequestMode, Response};
 use gloo_utils::format::JsValueSerdeExt;
 
+pub use crate::relay::capabilities::{AgentCapabilities, AgentCapability};
+pub use crate::relay::codecs::ViewerCapabilities;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Device {
     pub id: String,
@@ -16,11 +19,43 @@ pub struct Device {
     pub version: String,
     pub last_seen: Option<String>,
     pub is_online: bool,
+    /// `online`, `degraded` while it misses heartbeats, or `offline`
+    #[serde(default)]
+    pub status: String,
     pub owner_id: String,
     pub group_id: Option<String>,
     pub tags: Vec<String>,
     pub created_at: String,
     pub updated_at: String,
+    /// What the agent reported it can do
+    #[serde(default)]
+    pub capabilities: AgentCapabilities,
+}
+
+impl Device {
+    /// Why the device cannot serve `session_type`, worded for the launcher
+    pub fn unsupported(&self, session_type: &SessionType) -> Option<String> {
+        let required: &[AgentCapability] = match session_type {
+            SessionType::View => &[AgentCapability::ScreenCapture],
+            SessionType::Control => &[AgentCapability::ScreenCapture, AgentCapability::InputControl],
+            SessionType::FileTransfer => &[AgentCapability::FileTransfer],
+            SessionType::Terminal | SessionType::Backstage => &[AgentCapability::Terminal],
+        };
+        self.capabilities.check(required).err().map(|unsupported| unsupported.to_string())
+    }
+
+    /// Connected but missing heartbeats
+    pub fn is_degraded(&self) -> bool {
+        self.is_online && self.status == "degraded"
+    }
+
+    pub fn status_label(&self) -> &'static str {
+        match (self.is_online, self.is_degraded()) {
+            (true, true) => "Degraded",
+            (true, false) => "Online",
+            (false, _) => "Offline",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -34,6 +69,9 @@ pub struct Session {
     pub ended_at: Option<String>,
     pub ip_address: String,
     pub user_agent: String,
+    /// Ticket in the organization's PSA the session belongs to
+    #[serde(default)]
+    pub ticket_ref: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -42,6 +80,8 @@ pub enum SessionType {
     Control,
     FileTransfer,
     Terminal,
+    /// The device's shell, out of the end user's sight
+    Backstage,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -63,6 +103,202 @@ pub struct ServerStats {
 pub struct CreateSessionRequest {
     pub session_type: SessionType,
     pub user_id: Option<String>,
+    /// What this viewer decodes, so the agent picks a stream it can show
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<ViewerCapabilities>,
+    /// Why the session is needed, for whoever approves a backstage session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub justification: Option<String>,
+}
+
+/// A new session and the token its viewer attaches with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreatedSession {
+    pub session_id: String,
+    pub token: String,
+}
+
+/// A backstage session held until someone approves it
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct BackstageApproval {
+    pub id: String,
+    /// `pending`, `approved`, `denied`, `expired`, or `failed` when the
+    /// approved session could not start
+    pub state: String,
+    /// Why it was denied or failed
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    pub expires_at: String,
+}
+
+/// What asking for a session led to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionStart {
+    Started(CreatedSession),
+    /// The device's policy holds backstage sessions for approval
+    PendingApproval(BackstageApproval),
+}
+
+/// A file or directory on a session's device
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FsEntry {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub modified: Option<i64>,
+    pub permissions: String,
+    pub is_dir: bool,
+    pub is_symlink: bool,
+}
+
+/// One page of a remote directory
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DirectoryListing {
+    pub path: String,
+    pub entries: Vec<FsEntry>,
+    pub total: usize,
+    pub next_offset: Option<usize>,
+}
+
+/// Notes, custom fields, inventory and crashes from the device detail API
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DeviceDetails {
+    pub note: Option<DeviceNote>,
+    pub fields: Vec<DeviceField>,
+    pub inventory: Option<DeviceInventory>,
+    pub crashes: Option<CrashSummary>,
+}
+
+/// How often the device's agent crashed
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CrashSummary {
+    pub reports: usize,
+    pub last_crash_at: Option<String>,
+}
+
+/// Current revision of a device's note
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct DeviceNote {
+    /// Markdown
+    pub body: String,
+    pub author_email: Option<String>,
+    pub created_at: String,
+}
+
+/// A custom field of the device's organization and its value
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct DeviceField {
+    pub key: String,
+    pub label: String,
+    pub field_type: String,
+    pub value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct DeviceInventory {
+    pub inventory: Inventory,
+    pub collected_at: String,
+}
+
+/// What the agent last reported about its machine
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Inventory {
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    pub os_build: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    pub logged_in_user: Option<String>,
+    pub cpu: Option<String>,
+    pub memory_total: Option<u64>,
+    pub interfaces: Vec<InventoryInterface>,
+    pub disks: Vec<InventoryDisk>,
+    pub agent_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct InventoryInterface {
+    pub name: String,
+    pub mac: Option<String>,
+    pub ipv4: Vec<String>,
+    pub ipv6: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct InventoryDisk {
+    pub mount_point: String,
+    pub total_bytes: u64,
+}
+
+/// Filters for listing devices; unset fields don't filter
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceQuery {
+    /// Group ID, or `none` for devices in no group
+    pub group: Option<String>,
+    /// Tags the device must all carry
+    pub tags: Vec<String>,
+    pub online: Option<bool>,
+    pub platform: Option<String>,
+    /// Text to find in the name or hostname
+    pub q: Option<String>,
+    /// `name`, `hostname`, `platform`, `last_seen` or `status`
+    pub sort: Option<String>,
+    pub descending: bool,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl DeviceQuery {
+    /// Query string for `/api/devices`, empty when nothing is set
+    pub fn to_query_string(&self) -> String {
+        let mut params: Vec<(&str, String)> = Vec::new();
+        if let Some(group) = &self.group {
+            params.push(("group", group.clone()));
+        }
+        if !self.tags.is_empty() {
+            params.push(("tag", self.tags.join(",")));
+        }
+        if let Some(online) = self.online {
+            params.push(("online", online.to_string()));
+        }
+        if let Some(platform) = &self.platform {
+            params.push(("platform", platform.clone()));
+        }
+        if let Some(q) = &self.q {
+            params.push(("q", q.clone()));
+        }
+        if let Some(sort) = &self.sort {
+            params.push(("sort", sort.clone()));
+        }
+        if self.descending {
+            params.push(("order", "desc".to_string()));
+        }
+        if let Some(limit) = self.limit {
+            params.push(("limit", limit.to_string()));
+        }
+        if self.offset > 0 {
+            params.push(("offset", self.offset.to_string()));
+        }
+
+        let pairs: Vec<String> = params
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, String::from(js_sys::encode_uri_component(&value))))
+            .collect();
+        if pairs.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", pairs.join("&"))
+        }
+    }
 }
 
 pub struct ApiClient;
@@ -70,7 +306,13 @@ pub struct ApiClient;
 impl ApiClient {
     /// Fetch all connected devices
     pub async fn get_devices() -> Result<Vec<Device>, String> {
-        let response = Self::fetch("/api/devices", "GET", None::<()>).await?;
+        Self::find_devices(&DeviceQuery { online: Some(true), ..Default::default() }).await
+    }
+
+    /// Fetch the devices matching `query`
+    pub async fn find_devices(query: &DeviceQuery) -> Result<Vec<Device>, String> {
+        let url = format!("/api/devices{}", query.to_query_string());
+        let response = Self::fetch(&url, "GET", None::<()>).await?;
         let json: serde_json::Value = response.into_serde()
             .map_err(|e| format!("Failed to parse JSON: {}", e))?;
         
@@ -104,20 +346,57 @@ impl ApiClient {
         }
     }
 
-    /// Create a new session with a device
-    pub async fn create_session(device_id: &str, request: CreateSessionRequest) -> Result<String, String> {
+    /// Notes, custom fields and inventory of a device
+    pub async fn get_device_details(device_id: &str) -> Result<DeviceDetails, String> {
+        let url = format!("/api/devices/{}", device_id);
+        let response = Self::fetch(&url, "GET", None::<()>).await?;
+        response.into_serde()
+            .map_err(|e| format!("Failed to parse device details: {}", e))
+    }
+
+    /// Create a new session with a device, or ask for one held for approval
+    pub async fn create_session(device_id: &str, request: CreateSessionRequest) -> Result<SessionStart, String> {
         let url = format!("/api/devices/{}/sessions", device_id);
         let response = Self::fetch(&url, "POST", Some(request)).await?;
         let json: serde_json::Value = response.into_serde()
             .map_err(|e| format!("Failed to parse JSON: {}", e))?;
-        
-        if let Some(session_id) = json.get("session_id").and_then(|v| v.as_str()) {
-            Ok(session_id.to_string())
-        } else {
-            Err("Failed to create session".to_string())
+
+        if json.get("status").and_then(|v| v.as_str()) == Some("pending_approval") {
+            return serde_json::from_value(json["approval"].clone())
+                .map(SessionStart::PendingApproval)
+                .map_err(|e| format!("Failed to parse approval: {}", e));
+        }
+        match (
+            json.get("session_id").and_then(|v| v.as_str()),
+            json.get("token").and_then(|v| v.as_str()),
+        ) {
+            (Some(session_id), Some(token)) => Ok(SessionStart::Started(CreatedSession {
+                session_id: session_id.to_string(),
+                token: token.to_string(),
+            })),
+            _ => Err("Failed to create session".to_string()),
         }
     }
 
+    /// Where a held backstage session stands, and once approved the token
+    /// to attach to it with
+    pub async fn get_approval(approval_id: &str) -> Result<(BackstageApproval, Option<CreatedSession>), String> {
+        let url = format!("/api/approvals/{}", approval_id);
+        let response = Self::fetch(&url, "GET", None::<()>).await?;
+        let json: serde_json::Value = response.into_serde()
+            .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+        let approval: BackstageApproval = serde_json::from_value(json["approval"].clone())
+            .map_err(|e| format!("Failed to parse approval: {}", e))?;
+        let launch = match (&approval.session_id, json["launch"]["token"].as_str()) {
+            (Some(session_id), Some(token)) => Some(CreatedSession {
+                session_id: session_id.clone(),
+                token: token.to_string(),
+            }),
+            _ => None,
+        };
+        Ok((approval, launch))
+    }
+
     /// End a session
     pub async fn end_session(session_id: &str) -> Result<(), String> {
         let url = format!("/api/sessions/{}", session_id);
@@ -125,6 +404,54 @@ impl ApiClient {
         Ok(())
     }
 
+    /// List a directory on a session's device; an empty path lists the roots
+    pub async fn list_directory(session_id: &str, path: &str, offset: usize) -> Result<DirectoryListing, String> {
+        let url = format!(
+            "/api/sessions/{}/fs?path={}&offset={}",
+            session_id,
+            String::from(js_sys::encode_uri_component(path)),
+            offset
+        );
+        let response = Self::fetch(&url, "GET", None::<()>).await?;
+        response.into_serde()
+            .map_err(|e| format!("Failed to parse listing: {}", e))
+    }
+
+    /// Upload files to be dropped on a session's device; the agent's
+    /// progress arrives on the session WebSocket as `FileDropProgress`
+    pub async fn upload_files(session_id: &str, form: &web_sys::FormData) -> Result<String, String> {
+        let url = format!("/api/sessions/{}/files", session_id);
+        let mut opts = RequestInit::new();
+        opts.method("POST");
+        opts.mode(RequestMode::Cors);
+        // The browser sets the multipart boundary itself
+        opts.body(Some(form.as_ref()));
+
+        let request = Request::new_with_str_and_init(&url, &opts)
+            .map_err(|_| "Failed to create request".to_string())?;
+        let window = web_sys::window().ok_or("No window available")?;
+        let resp: Response = JsFuture::from(window.fetch_with_request(&request))
+            .await
+            .map_err(|_| "Upload failed".to_string())?
+            .dyn_into()
+            .map_err(|_| "Failed to cast response".to_string())?;
+
+        let json: serde_json::Value = match resp.json() {
+            Ok(json) => JsFuture::from(json).await.ok()
+                .and_then(|json| json.into_serde().ok())
+                .unwrap_or_default(),
+            Err(_) => serde_json::Value::Null,
+        };
+        if !resp.ok() {
+            return Err(json.get("error").and_then(|v| v.as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("Upload failed with status: {}", resp.status())));
+        }
+        json.get("upload_id").and_then(|v| v.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| "Invalid response format".to_string())
+    }
+
     /// Generic fetch function
     async fn fetch<T: Serialize>(
         url: &str,
@@ -159,7 +486,15 @@ impl ApiClient {
             .map_err(|_| "Failed to cast response".to_string())?;
 
         if !resp.ok() {
-            return Err(format!("Request failed with status: {}", resp.status()));
+            // Refusals say why in `error`, e.g. a device that only supports viewing
+            let status = resp.status();
+            let reason = match resp.json() {
+                Ok(body) => JsFuture::from(body).await.ok()
+                    .and_then(|body| body.into_serde::<serde_json::Value>().ok())
+                    .and_then(|body| body.get("error").and_then(|error| error.as_str()).map(str::to_string)),
+                Err(_) => None,
+            };
+            return Err(reason.unwrap_or_else(|| format!("Request failed with status: {}", status)));
         }
 
         let json = JsFuture::from(resp.json().map_err(|_| "Failed to get JSON from response")?)
@@ -171,16 +506,22 @@ impl ApiClient {
 }
 
 /// Create a WebSocket connection for real-time session data
-pub fn create_session_websocket(session_id: &str) -> Result<web_sys::WebSocket, String> {
+pub fn create_session_websocket(session_id: &str, token: &str) -> Result<web_sys::WebSocket, String> {
     let window = web_sys::window().ok_or("No window available")?;
     let location = window.location();
     let protocol = if location.protocol().unwrap_or_default() == "https:" { "wss:" } else { "ws:" };
     let host = location.host().map_err(|_| "Failed to get host")?;
     
-    let ws_url = format!("{}//{}//api/ws?session_id={}&type=viewer", protocol, host, session_id);
+    let ws_url = format!(
+        "{}//{}/api/ws?session_id={}&type=viewer&token={}",
+        protocol, host, session_id, js_sys::encode_uri_component(token)
+    );
     
-    web_sys::WebSocket::new(&ws_url)
-        .map_err(|_| "Failed to create WebSocket".to_string())
+    let websocket = web_sys::WebSocket::new(&ws_url)
+        .map_err(|_| "Failed to create WebSocket".to_string())?;
+    // Screen frames arrive as binary messages
+    websocket.set_binary_type(web_sys::BinaryType::Arraybuffer);
+    Ok(websocket)
 }
 
 /// Format timestamp for display
@@ -195,13 +536,21 @@ pub fn format_timestamp(timestamp: &str) -> String {
 }
 
 /// Get status badge class for devices
-pub fn get_status_badge_class(is_online: bool) -> &'static str {
-    if is_online { "bg-success" } else { "bg-secondary" }
+pub fn get_status_badge_class(device: &Device) -> &'static str {
+    match (device.is_online, device.is_degraded()) {
+        (true, true) => "bg-warning text-dark",
+        (true, false) => "bg-success",
+        (false, _) => "bg-secondary",
+    }
 }
 
 /// Get status icon for devices
-pub fn get_status_icon(is_online: bool) -> &'static str {
-    if is_online { "bi-circle-fill" } else { "bi-circle" }
+pub fn get_status_icon(device: &Device) -> &'static str {
+    match (device.is_online, device.is_degraded()) {
+        (true, true) => "bi-exclamation-circle-fill",
+        (true, false) => "bi-circle-fill",
+        (false, _) => "bi-circle",
+    }
 }
 
 /// Get platform icon for devices
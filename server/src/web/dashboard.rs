@@ -1,7 +1,7 @@
 use leptos::*;
 use leptos_router::*;
 use crate::web::api_client::*;
-use std::time::Duration;
+use crate::web::realtime::{ConnectionStatus, RealtimeClient};
 
 #[component]
 pub fn Dashboard() -> impl IntoView {
@@ -40,22 +40,23 @@ pub fn Dashboard() -> impl IntoView {
         });
     });
 
-    // Set up auto-refresh every 30 seconds
-    create_effect(move |_| {
-        set_interval(
-            move || {
-                spawn_local(async move {
-                    if let Ok(device_list) = ApiClient::get_devices().await {
-                        set_devices.set(device_list);
-                    }
-                    if let Ok(server_stats) = ApiClient::get_stats().await {
-                        set_stats.set(Some(server_stats));
-                    }
-                });
-            },
-            Duration::from_secs(30),
-        );
+    // Keep devices and stats current from the events socket instead of polling
+    let realtime = RealtimeClient::new();
+    if let Err(e) = realtime.connect() {
+        logging::log!("Failed to open live updates: {:?}", e);
+    }
+    let live_state = realtime.state();
+    create_effect(move |first_run: Option<()>| {
+        let state = live_state.get();
+        // The REST load covers the page until the first snapshot arrives
+        if first_run.is_some() {
+            set_stats.set(Some(state.stats()));
+            set_devices.set(state.devices);
+            set_loading.set(false);
+        }
     });
+    let connection_state = realtime.connection_state();
+    on_cleanup(move || realtime.disconnect());
 
     // Filter devices based on search and platform
     let filtered_devices = create_memo(move |_| {
@@ -92,7 +93,8 @@ pub fn Dashboard() -> impl IntoView {
                             <i class="bi bi-speedometer2 me-2 text-primary"></i>
                             "Dashboard"
                         </h1>
-                        <div class="d-flex gap-2">
+                        <div class="d-flex gap-2 align-items-center">
+                            <ConnectionStatus state=connection_state />
                             <button 
                                 class="btn btn-outline-primary btn-sm"
                                 on:click=move |_| {
@@ -327,6 +329,118 @@ pub fn Dashboard() -> impl IntoView {
     }
 }
 
+/// Note, custom fields, inventory and crashes below a device card
+#[component]
+fn DeviceDetailsPanel(details: DeviceDetails) -> impl IntoView {
+    let crashes = details.crashes
+        .and_then(|crashes| crashes.last_crash_at.map(|last| (crashes.reports, last)))
+        .map(|(reports, last)| view! {
+            <div class="alert alert-warning small py-1 px-2 mb-2">
+                <i class="fas fa-bug me-1"></i>
+                {format!("Agent last crashed {} ({} reports)", format_timestamp(&last), reports)}
+            </div>
+        });
+    let note = details.note.map(|note| {
+        let written = format!(
+            "{} {}",
+            note.author_email.unwrap_or_else(|| "Unknown".to_string()),
+            format_timestamp(&note.created_at)
+        );
+        view! {
+            <div class="mb-2">
+                // Markdown is shown as written
+                <div class="small" style="white-space: pre-wrap">{note.body}</div>
+                <div class="text-muted small">{written}</div>
+            </div>
+        }
+    });
+    let fields: Vec<_> = details.fields.into_iter()
+        .map(|field| {
+            let value = match field.value {
+                Some(serde_json::Value::String(text)) => text,
+                Some(value) => value.to_string(),
+                None => "-".to_string(),
+            };
+            view! {
+                <tr><td class="text-muted">{field.label}</td><td>{value}</td></tr>
+            }
+        })
+        .collect();
+    let inventory = details.inventory.map(|latest| {
+        let inventory = latest.inventory;
+        let os = [inventory.os_name, inventory.os_version, inventory.os_build.map(|build| format!("({})", build))]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let hardware = [inventory.manufacturer, inventory.model].into_iter().flatten().collect::<Vec<_>>().join(" ");
+        let disks = inventory.disks.iter()
+            .map(|disk| format!("{} {}", disk.mount_point, format_bytes(disk.total_bytes)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let interfaces = inventory.interfaces.iter()
+            .map(|interface| format!(
+                "{} {} {}",
+                interface.name,
+                interface.mac.clone().unwrap_or_default(),
+                interface.ipv4.iter().chain(&interface.ipv6).cloned().collect::<Vec<_>>().join(" ")
+            ))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let rows = [
+            ("OS", os),
+            ("Hardware", hardware),
+            ("Serial", inventory.serial_number.unwrap_or_default()),
+            ("CPU", inventory.cpu.unwrap_or_default()),
+            ("Memory", inventory.memory_total.map(format_bytes).unwrap_or_default()),
+            ("Disks", disks),
+            ("Network", interfaces),
+            ("User", inventory.logged_in_user.unwrap_or_default()),
+            ("Agent", inventory.agent_version.unwrap_or_default()),
+            ("Collected", format_timestamp(&latest.collected_at)),
+        ];
+        rows.into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(label, value)| view! {
+                <tr><td class="text-muted">{label}</td><td>{value}</td></tr>
+            })
+            .collect::<Vec<_>>()
+    });
+
+    view! {
+        <div class="border-top mt-3 pt-2">
+            {crashes}
+            <h6 class="small fw-bold">"Notes"</h6>
+            {note.unwrap_or_else(|| view! {
+                <div class="mb-2 small text-muted">"No notes"</div>
+            })}
+            {(!fields.is_empty()).then(|| view! {
+                <h6 class="small fw-bold">"Fields"</h6>
+                <table class="table table-sm small mb-2"><tbody>{fields}</tbody></table>
+            })}
+            <h6 class="small fw-bold">"Inventory"</h6>
+            {match inventory {
+                Some(rows) => view! {
+                    <table class="table table-sm small mb-0"><tbody>{rows}</tbody></table>
+                }.into_view(),
+                None => view! { <div class="small text-muted">"Not reported yet"</div> }.into_view(),
+            }}
+        </div>
+    }
+}
+
+/// Sizes of disks and memory
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.0} {}", size, UNITS[unit])
+}
+
 #[component]
 fn StatsCard(
     icon: &'static str,
@@ -351,10 +465,44 @@ fn StatsCard(
     }
 }
 
+/// Open a session's viewer in a new tab
+fn open_session(session: &CreatedSession) {
+    if let Some(window) = web_sys::window() {
+        let session_url = format!(
+            "/session/{}?token={}",
+            session.session_id,
+            js_sys::encode_uri_component(&session.token)
+        );
+        let _ = window.open_with_url_and_target(&session_url, "_blank");
+    }
+}
+
+/// Alert class and wording of where a backstage request stands
+fn approval_status(approval: &BackstageApproval) -> (&'static str, String) {
+    let reason = approval.reason.as_ref().map(|reason| format!(": {}", reason)).unwrap_or_default();
+    match approval.state.as_str() {
+        "pending" => ("alert-info", format!("Waiting for approval until {}", format_timestamp(&approval.expires_at))),
+        "approved" => ("alert-success", "Approved; the session opened in a new tab".to_string()),
+        "denied" => ("alert-warning", format!("Backstage request denied{}", reason)),
+        "expired" => ("alert-warning", "Backstage request expired without a decision".to_string()),
+        _ => ("alert-danger", format!("Approved, but the session could not start{}", reason)),
+    }
+}
+
 #[component]
 fn DeviceCard(device: Device) -> impl IntoView {
     let (sessions, set_sessions) = create_signal(Vec::<Session>::new());
     let (connecting, set_connecting) = create_signal(false);
+    let (connect_error, set_connect_error) = create_signal(None::<String>);
+    let (show_details, set_show_details) = create_signal(false);
+    let (details, set_details) = create_signal(None::<Result<DeviceDetails, String>>);
+    let (approval, set_approval) = create_signal(None::<BackstageApproval>);
+    let approval_poll = store_value(None::<leptos::leptos_dom::helpers::IntervalHandle>);
+    on_cleanup(move || {
+        if let Some(handle) = approval_poll.get_value() {
+            handle.clear();
+        }
+    });
 
     // Load sessions for this device
     let device_id = device.id.clone();
@@ -367,34 +515,89 @@ fn DeviceCard(device: Device) -> impl IntoView {
         });
     });
 
-    let status_badge_class = get_status_badge_class(device.is_online);
-    let status_icon = get_status_icon(device.is_online);
+    let status_badge_class = get_status_badge_class(&device);
+    let status_icon = get_status_icon(&device);
     let platform_icon = get_platform_icon(&device.platform);
+    let is_online = device.is_online;
+
+    // Sessions the agent said it cannot serve are greyed out with the reason
+    let control_unsupported = device.unsupported(&SessionType::Control);
+    let view_unsupported = device.unsupported(&SessionType::View);
+    let file_unsupported = device.unsupported(&SessionType::FileTransfer);
+    let terminal_unsupported = device.unsupported(&SessionType::Terminal);
+    let backstage_unsupported = device.unsupported(&SessionType::Backstage);
+
+    // A held backstage session is checked on until it is decided; the
+    // approved one opens like any other
+    let watch_approval = move |approval_id: String| {
+        let poll = move || {
+            let approval_id = approval_id.clone();
+            spawn_local(async move {
+                match ApiClient::get_approval(&approval_id).await {
+                    Ok((current, launch)) => {
+                        if current.state != "pending" {
+                            if let Some(handle) = approval_poll.get_value() {
+                                handle.clear();
+                            }
+                            approval_poll.set_value(None);
+                        }
+                        if let Some(session) = launch {
+                            open_session(&session);
+                        }
+                        set_approval.set(Some(current));
+                    }
+                    Err(e) => logging::log!("Failed to check approval {}: {}", approval_id, e),
+                }
+            });
+        };
+        if let Some(previous) = approval_poll.get_value() {
+            previous.clear();
+        }
+        match set_interval_with_handle(poll, std::time::Duration::from_secs(5)) {
+            Ok(handle) => approval_poll.set_value(Some(handle)),
+            Err(e) => logging::log!("Failed to watch approval: {:?}", e),
+        }
+    };
 
     let handle_connect = {
         let device_id = device.id.clone();
         move |session_type: SessionType| {
             let device_id = device_id.clone();
+            // Whoever approves a backstage session is told why it is needed
+            let justification = match session_type {
+                SessionType::Backstage => {
+                    let answer = web_sys::window()
+                        .and_then(|window| window.prompt_with_message("Why do you need backstage access?").ok().flatten());
+                    let Some(answer) = answer else { return };
+                    Some(answer.trim().to_string()).filter(|answer| !answer.is_empty())
+                }
+                _ => None,
+            };
+            let watch_approval = watch_approval.clone();
             spawn_local(async move {
                 set_connecting.set(true);
+                set_connect_error.set(None);
                 
                 let request = CreateSessionRequest {
                     session_type,
                     user_id: None, // Will use current user
+                    capabilities: Some(crate::web::video::viewer_capabilities().await),
+                    justification,
                 };
 
                 match ApiClient::create_session(&device_id, request).await {
-                    Ok(session_id) => {
-                        logging::log!("Session created: {}", session_id);
-                        // Navigate to session view or open in popup
-                        if let Some(window) = web_sys::window() {
-                            let session_url = format!("/session/{}", session_id);
-                            let _ = window.open_with_url_and_target(&session_url, "_blank");
-                        }
+                    Ok(SessionStart::Started(session)) => {
+                        logging::log!("Session created: {}", session.session_id);
+                        open_session(&session);
+                    }
+                    Ok(SessionStart::PendingApproval(pending)) => {
+                        logging::log!("Backstage session waits for approval {}", pending.id);
+                        watch_approval(pending.id.clone());
+                        set_approval.set(Some(pending));
                     }
                     Err(e) => {
                         logging::log!("Failed to create session: {}", e);
-                        // Show error notification
+                        set_connect_error.set(Some(e));
                     }
                 }
                 
@@ -418,7 +621,7 @@ fn DeviceCard(device: Device) -> impl IntoView {
                     </div>
                     <span class={format!("badge {}", status_badge_class)}>
                         <i class={format!("bi {} me-1", status_icon)}></i>
-                        {if device.is_online { "Online" } else { "Offline" }}
+                        {device.status_label()}
                     </span>
                 </div>
                 
@@ -461,7 +664,11 @@ fn DeviceCard(device: Device) -> impl IntoView {
                 <div class="d-grid gap-2">
                     <button
                         class="btn btn-primary btn-sm"
-                        disabled=move || !device.is_online || connecting.get()
+                        disabled={
+                            let unsupported = control_unsupported.is_some();
+                            move || !is_online || connecting.get() || unsupported
+                        }
+                        title=control_unsupported.clone()
                         on:click={
                             let handle = handle_connect.clone();
                             move |_| handle(SessionType::Control)
@@ -488,8 +695,11 @@ fn DeviceCard(device: Device) -> impl IntoView {
                         <button 
                             type="button" 
                             class="btn btn-outline-secondary btn-sm"
-                            disabled=move || !device.is_online
-                            title="View Only"
+                            disabled={
+                                let unsupported = view_unsupported.is_some();
+                                move || !is_online || unsupported
+                            }
+                            title=view_unsupported.clone().unwrap_or_else(|| "View Only".to_string())
                             on:click={
                                 let handle = handle_connect.clone();
                                 move |_| handle(SessionType::View)
@@ -500,8 +710,11 @@ fn DeviceCard(device: Device) -> impl IntoView {
                         <button 
                             type="button" 
                             class="btn btn-outline-secondary btn-sm"
-                            disabled=move || !device.is_online
-                            title="File Transfer"
+                            disabled={
+                                let unsupported = file_unsupported.is_some();
+                                move || !is_online || unsupported
+                            }
+                            title=file_unsupported.clone().unwrap_or_else(|| "File Transfer".to_string())
                             on:click={
                                 let handle = handle_connect.clone();
                                 move |_| handle(SessionType::FileTransfer)
@@ -512,8 +725,11 @@ fn DeviceCard(device: Device) -> impl IntoView {
                         <button 
                             type="button" 
                             class="btn btn-outline-secondary btn-sm"
-                            disabled=move || !device.is_online
-                            title="Terminal"
+                            disabled={
+                                let unsupported = terminal_unsupported.is_some();
+                                move || !is_online || unsupported
+                            }
+                            title=terminal_unsupported.clone().unwrap_or_else(|| "Terminal".to_string())
                             on:click={
                                 let handle = handle_connect.clone();
                                 move |_| handle(SessionType::Terminal)
@@ -524,33 +740,64 @@ fn DeviceCard(device: Device) -> impl IntoView {
                         <button 
                             type="button" 
                             class="btn btn-outline-secondary btn-sm"
-                            title="Device Settings"
+                            disabled={
+                                let unsupported = backstage_unsupported.is_some();
+                                move || !is_online || unsupported || approval.get().is_some_and(|approval| approval.state == "pending")
+                            }
+                            title=backstage_unsupported.clone().unwrap_or_else(|| "Backstage".to_string())
+                            on:click={
+                                let handle = handle_connect.clone();
+                                move |_| handle(SessionType::Backstage)
+                            }
+                        >
+                            <i class="bi bi-incognito"></i>
+                        </button>
+                        <button 
+                            type="button" 
+                            class="btn btn-outline-secondary btn-sm"
+                            title="Notes, Fields and Inventory"
+                            on:click={
+                                let device_id = device.id.clone();
+                                move |_| {
+                                    let opening = !show_details.get();
+                                    set_show_details.set(opening);
+                                    if !opening {
+                                        return;
+                                    }
+                                    // Fetched on every opening; inventory and notes change
+                                    let device_id = device_id.clone();
+                                    spawn_local(async move {
+                                        set_details.set(Some(ApiClient::get_device_details(&device_id).await));
+                                    });
+                                }
+                            }
                         >
                             <i class="bi bi-gear"></i>
                         </button>
                     </div>
                 </div>
+
+                {move || connect_error.get().map(|error| view! {
+                    <div class="alert alert-warning small py-1 px-2 mt-2 mb-0">{error}</div>
+                })}
+
+                {move || approval.get().map(|approval| {
+                    let (class, message) = approval_status(&approval);
+                    view! {
+                        <div class={format!("alert {} small py-1 px-2 mt-2 mb-0", class)}>{message}</div>
+                    }
+                })}
+
+                {move || show_details.get().then(|| match details.get() {
+                    None => view! {
+                        <div class="small text-muted mt-2">"Loading..."</div>
+                    }.into_view(),
+                    Some(Err(error)) => view! {
+                        <div class="alert alert-warning small py-1 px-2 mt-2 mb-0">{error}</div>
+                    }.into_view(),
+                    Some(Ok(details)) => view! { <DeviceDetailsPanel details=details/> }.into_view(),
+                })}
             </div>
         </div>
     }
 }
-
-fn set_interval<F>(f: F, delay: Duration) 
-where
-    F: Fn() + 'static,
-{
-    use wasm_bindgen::closure::Closure;
-    use wasm_bindgen::JsCast;
-    
-    let closure = Closure::wrap(Box::new(f) as Box<dyn Fn()>);
-    
-    web_sys::window()
-        .unwrap()
-        .set_interval_with_callback_and_timeout_and_arguments_0(
-            closure.as_ref().unchecked_ref(),
-            delay.as_millis() as i32,
-        )
-        .expect("should register interval");
-        
-    closure.forget();
-}
\ No newline at end of file
@@ -1285,19 +1285,7 @@ where
                 Ok(config) => set_config_content.set(config),
                 Err(_) => {
                     // Fallback configuration
-                    let config = format!(
-                        r#"{{
-    "server_url": "https://your-ghostlink-server.com",
-    "device_name": "My-{}-Device",
-    "organization_id": "your-org-id",
-    "auto_connect": true,
-    "security": {{
-        "require_approval": true,
-        "allow_file_transfer": true,
-        "allow_terminal": false
-    }}
-}}"#, platform.to_uppercase()
-                    );
+                    let config = client_config("wss://your-ghostlink-server.com/relay/ws", &platform);
                     set_config_content.set(config);
                 }
             }
@@ -1367,19 +1355,24 @@ where
                                     <p class="mb-0">
                                         "Save this file as " <code>"config.json"</code> " in the GhostLink installation directory "
                                         <code>"C:\\Program Files\\GhostLink\\config.json"</code>
+                                        " and point the agent at it with " <code>"--config"</code>
+                                        " or the " <code>"GHOSTLINK_CONFIG"</code> " environment variable"
                                     </p>
                                 }.into_view(),
                                 "macos" => view! {
                                     <p class="mb-0">
                                         "Save this file as " <code>"config.json"</code> " in "
                                         <code>"/Applications/GhostLink.app/Contents/Resources/config.json"</code>
+                                        " and point the agent at it with " <code>"--config"</code>
+                                        " or the " <code>"GHOSTLINK_CONFIG"</code> " environment variable"
                                     </p>
                                 }.into_view(),
                                 "linux" => view! {
                                     <p class="mb-0">
                                         "Save this file as " <code>"config.json"</code> " in "
-                                        <code>"/etc/ghostlink/config.json"</code> " or "
-                                        <code>"~/.config/ghostlink/config.json"</code>
+                                        <code>"/etc/ghostlink/config.json"</code>
+                                        " and start the agent with " <code>"ghostlink-client start --config /etc/ghostlink/config.json"</code>
+                                        "; " <code>"ghostlink-client config validate"</code> " checks it first"
                                     </p>
                                 }.into_view(),
                                 _ => view! {
@@ -1455,11 +1448,13 @@ where
                     version: "1.0.0".to_string(),
                     last_seen: Some(chrono::Utc::now().to_rfc3339()),
                     is_online: true,
+                    status: "online".to_string(),
                     owner_id: "current-user".to_string(),
                     group_id: None,
                     tags: vec![],
                     created_at: chrono::Utc::now().to_rfc3339(),
                     updated_at: chrono::Utc::now().to_rfc3339(),
+                    capabilities: AgentCapabilities::default(),
                 };
                 set_device_info.set(Some(mock_device));
             },
@@ -1659,19 +1654,24 @@ async fn get_installation_instructions(platform: &str) -> Result<InstallationIns
     })
 }
 
-async fn generate_config_file(_platform: &str) -> Result<String, String> {
-    // This would generate a proper config file with server details
-    Ok(r#"{
-    "server_url": "https://your-ghostlink-server.com",
-    "device_name": "My-Device",
-    "organization_id": "your-org-id",
-    "auto_connect": true,
-    "security": {
-        "require_approval": true,
-        "allow_file_transfer": true,
-        "allow_terminal": false
-    }
-}"#.to_string())
+async fn generate_config_file(platform: &str) -> Result<String, String> {
+    let window = web_sys::window().ok_or("No window available")?;
+    let location = window.location();
+    let protocol = if location.protocol().unwrap_or_default() == "https:" { "wss:" } else { "ws:" };
+    let host = location.host().map_err(|_| "Failed to get host")?;
+    Ok(client_config(&format!("{}//{}/relay/ws", protocol, host), platform))
+}
+
+/// Agent config in the client's JSON schema; keys the client does not
+/// know are refused, so only those it reads are written
+fn client_config(server_url: &str, platform: &str) -> String {
+    let config = serde_json::json!({
+        "server_url": server_url,
+        "hostname": format!("My-{}-Device", platform.to_uppercase()),
+        "heartbeat_interval": 30,
+        "log_level": "info",
+    });
+    serde_json::to_string_pretty(&config).unwrap_or_default()
 }
 
 fn get_default_steps(platform: &str) -> Vec<String> {
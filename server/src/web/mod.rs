@@ -1,6 +1,9 @@
 // Main app module - needs to compile for server SSR
 pub mod app;
 
+// Stream parsing and input mapping, shared with host tests
+pub mod stream;
+
 // For SSR (server-side rendering), we need stub modules that provide the same interface
 // The actual implementations with browser APIs are only compiled for WASM
 
@@ -21,6 +24,8 @@ pub mod monitor_controls;
 pub mod realtime;
 #[cfg(target_arch = "wasm32")]
 pub mod session_launcher;
+#[cfg(target_arch = "wasm32")]
+pub mod video;
 
 // Server-side stub modules that provide the same component signatures
 // These are used during SSR to render placeholder content
@@ -1,17 +1,24 @@
+//! Live dashboard updates from `/api/ws/events`: a snapshot when the socket
+//! opens, then device, session and relay node changes as they happen. The
+//! client reconnects on its own, resubscribing to the same filter.
+
 use leptos::*;
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 use web_sys::{MessageEvent, WebSocket, CloseEvent, Event};
 use tracing::{info, warn, error};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
 
-/// Real-time WebSocket client for live updates
-#[derive(Clone)]
-pub struct RealtimeClient {
-    websocket: Option<WebSocket>,
-    connection_state: RwSignal<ConnectionState>,
-    message_handler: RwSignal<Option<Box<dyn Fn(RealtimeMessage)>>>,
-}
+use crate::web::api_client::{AgentCapabilities, Device, ServerStats};
+
+/// How often the client pings so the server keeps the connection
+const PING_INTERVAL: Duration = Duration::from_secs(25);
+/// First reconnect delay, doubled per failed attempt up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ConnectionState {
@@ -22,47 +29,209 @@ pub enum ConnectionState {
     Error(String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum RealtimeMessage {
-    DeviceStatusUpdate {
-        device_id: String,
-        is_online: bool,
-        last_seen: String,
-    },
-    SessionUpdate {
-        session_id: String,
-        device_id: String,
-        status: String,
-        user_name: String,
-    },
-    SystemStats {
-        connected_devices: usize,
-        active_sessions: usize,
-        bandwidth_usage: f64,
-        cpu_usage: f64,
-    },
-    DeviceConnected {
-        device_id: String,
-        device_name: String,
-        platform: String,
-    },
-    DeviceDisconnected {
-        device_id: String,
-    },
-    Notification {
-        level: String, // "info", "warning", "error"
-        title: String,
-        message: String,
-        timestamp: String,
+/// Device as the events socket sends it
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiveDevice {
+    pub id: String,
+    pub organization_id: Option<String>,
+    pub name: String,
+    pub hostname: Option<String>,
+    pub platform: String,
+    pub architecture: Option<String>,
+    pub agent_version: Option<String>,
+    pub last_seen: Option<String>,
+    pub online: bool,
+    #[serde(default)]
+    pub status: String,
+    pub group_id: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub created_at: String,
+    pub updated_at: String,
+    #[serde(default)]
+    pub capabilities: AgentCapabilities,
+}
+
+impl From<LiveDevice> for Device {
+    fn from(device: LiveDevice) -> Self {
+        Device {
+            id: device.id,
+            name: device.name,
+            hostname: device.hostname.unwrap_or_default(),
+            platform: device.platform,
+            architecture: device.architecture.unwrap_or_default(),
+            version: device.agent_version.unwrap_or_default(),
+            last_seen: device.last_seen,
+            is_online: device.online,
+            status: device.status,
+            owner_id: device.organization_id.unwrap_or_default(),
+            group_id: device.group_id,
+            tags: device.tags,
+            created_at: device.created_at,
+            updated_at: device.updated_at,
+            capabilities: device.capabilities,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiveSession {
+    pub id: String,
+    pub agent_id: String,
+    pub status: String,
+}
+
+/// Heartbeat resource usage
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiveMetrics {
+    pub recorded_at: String,
+    pub cpu_usage: f32,
+    pub memory_used: u64,
+    pub memory_total: u64,
+    pub disk_used: u64,
+    pub disk_total: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiveRelayNode {
+    pub id: String,
+    pub region: String,
+    pub current_load: u32,
+    pub capacity: u32,
+    pub health_score: f32,
+    pub status: String,
+}
+
+/// Message from the events socket
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum LiveMessage {
+    Snapshot {
+        devices: Vec<LiveDevice>,
+        sessions: Vec<LiveSession>,
+        metrics: HashMap<String, LiveMetrics>,
+        relay_nodes: Vec<LiveRelayNode>,
     },
+    DeviceOnline { device: LiveDevice },
+    DeviceOffline { device_id: String, last_seen: Option<String> },
+    DeviceDegraded { device_id: String, last_seen: Option<String> },
+    DeviceMetrics { device_id: String, metrics: LiveMetrics },
+    SessionStarted { session: LiveSession },
+    SessionEnded { session: LiveSession },
+    RelayNodeHealth { node: LiveRelayNode },
+    RelayNodeRemoved { node_id: String },
+    Pong,
+    Error { error: String },
+}
+
+/// Which devices to hear about; unset fields match everything
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LiveSubscription {
+    /// Group ID, or `none` for devices in no group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_ids: Option<Vec<String>>,
+}
+
+/// Dashboard state kept current by the events socket
+#[derive(Debug, Clone, Default)]
+pub struct LiveState {
+    /// Online devices
+    pub devices: Vec<Device>,
+    /// Active session IDs and the device each is on
+    pub sessions: HashMap<String, String>,
+    pub metrics: HashMap<String, LiveMetrics>,
+    pub relay_nodes: Vec<LiveRelayNode>,
+}
+
+impl LiveState {
+    /// Fold a snapshot or event into the state
+    pub fn apply(&mut self, message: LiveMessage) {
+        match message {
+            LiveMessage::Snapshot { devices, sessions, metrics, relay_nodes } => {
+                self.devices = devices.into_iter().filter(|device| device.online).map(Device::from).collect();
+                self.sessions = sessions.into_iter().map(|session| (session.id, session.agent_id)).collect();
+                self.metrics = metrics;
+                self.relay_nodes = relay_nodes;
+            }
+            LiveMessage::DeviceOnline { device } => {
+                let device = Device::from(device);
+                match self.devices.iter_mut().find(|known| known.id == device.id) {
+                    Some(known) => *known = device,
+                    None => self.devices.push(device),
+                }
+            }
+            LiveMessage::DeviceOffline { device_id, .. } => {
+                self.devices.retain(|device| device.id != device_id);
+                self.metrics.remove(&device_id);
+            }
+            LiveMessage::DeviceDegraded { device_id, last_seen } => {
+                if let Some(device) = self.devices.iter_mut().find(|device| device.id == device_id) {
+                    device.status = "degraded".to_string();
+                    device.last_seen = last_seen;
+                }
+            }
+            LiveMessage::DeviceMetrics { device_id, metrics } => {
+                self.metrics.insert(device_id, metrics);
+            }
+            LiveMessage::SessionStarted { session } => {
+                self.sessions.insert(session.id, session.agent_id);
+            }
+            LiveMessage::SessionEnded { session } => {
+                self.sessions.remove(&session.id);
+            }
+            LiveMessage::RelayNodeHealth { node } => {
+                match self.relay_nodes.iter_mut().find(|known| known.id == node.id) {
+                    Some(known) => *known = node,
+                    None => self.relay_nodes.push(node),
+                }
+            }
+            LiveMessage::RelayNodeRemoved { node_id } => {
+                self.relay_nodes.retain(|node| node.id != node_id);
+            }
+            LiveMessage::Pong | LiveMessage::Error { .. } => {}
+        }
+    }
+
+    /// Dashboard counters for the current state
+    pub fn stats(&self) -> ServerStats {
+        let mut devices_by_platform = HashMap::new();
+        for device in &self.devices {
+            *devices_by_platform.entry(device.platform.to_lowercase()).or_insert(0) += 1;
+        }
+        ServerStats {
+            connected_devices: self.devices.len(),
+            active_sessions: self.sessions.len(),
+            devices_by_platform,
+        }
+    }
+}
+
+/// Events socket client feeding a [`LiveState`] signal
+#[derive(Clone)]
+pub struct RealtimeClient {
+    inner: Rc<RefCell<Connection>>,
+    connection_state: RwSignal<ConnectionState>,
+    state: RwSignal<LiveState>,
+}
+
+#[derive(Default)]
+struct Connection {
+    websocket: Option<WebSocket>,
+    subscription: LiveSubscription,
+    /// Set by `disconnect` so a close does not reconnect
+    closed: bool,
+    reconnect_delay: Option<Duration>,
+    keepalive_started: bool,
 }
 
 impl RealtimeClient {
     pub fn new() -> Self {
         Self {
-            websocket: None,
+            inner: Rc::new(RefCell::new(Connection::default())),
             connection_state: create_rw_signal(ConnectionState::Disconnected),
-            message_handler: create_rw_signal(None),
+            state: create_rw_signal(LiveState::default()),
         }
     }
 
@@ -70,399 +239,206 @@ impl RealtimeClient {
         self.connection_state.read_only()
     }
 
-    pub fn connect(&mut self, url: &str) -> Result<(), JsValue> {
-        if matches!(self.connection_state.get(), ConnectionState::Connected | ConnectionState::Connecting) {
+    pub fn state(&self) -> ReadSignal<LiveState> {
+        self.state.read_only()
+    }
+
+    /// Open the events socket, reconnecting whenever it drops
+    pub fn connect(&self) -> Result<(), JsValue> {
+        if matches!(self.connection_state.get_untracked(), ConnectionState::Connected | ConnectionState::Connecting) {
             return Ok(());
         }
+        self.inner.borrow_mut().closed = false;
+        self.open()
+    }
 
+    fn open(&self) -> Result<(), JsValue> {
         self.connection_state.set(ConnectionState::Connecting);
+        let ws = WebSocket::new(&events_url()?)?;
 
-        let ws = WebSocket::new(url)?;
-        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
-
-        // Clone signals for closures
-        let connection_state = self.connection_state;
-        let message_handler = self.message_handler;
-
-        // Handle WebSocket open
+        let client = self.clone();
         let onopen_callback = Closure::wrap(Box::new(move |_: Event| {
-            info!("WebSocket connected");
-            connection_state.set(ConnectionState::Connected);
+            info!("Events WebSocket connected");
+            client.connection_state.set(ConnectionState::Connected);
+            let mut inner = client.inner.borrow_mut();
+            inner.reconnect_delay = None;
+            // The server starts everyone on the unfiltered snapshot
+            if inner.subscription != LiveSubscription::default() {
+                let subscription = inner.subscription.clone();
+                drop(inner);
+                client.send_subscription(&subscription);
+            }
         }) as Box<dyn FnMut(Event)>);
         ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
         onopen_callback.forget();
 
-        // Handle WebSocket messages
+        let state = self.state;
         let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
-            if let Ok(text) = e.data().dyn_into::<js_sys::JsString>() {
-                let message_str = String::from(text);
-                match serde_json::from_str::<RealtimeMessage>(&message_str) {
-                    Ok(message) => {
-                        if let Some(handler) = message_handler.get() {
-                            handler(message);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to parse WebSocket message: {}", e);
-                    }
+            let Ok(text) = e.data().dyn_into::<js_sys::JsString>() else { return };
+            match serde_json::from_str::<LiveMessage>(&String::from(text)) {
+                Ok(LiveMessage::Pong) => {}
+                Ok(LiveMessage::Error { error }) => {
+                    warn!("Events socket rejected a message: {}", error);
+                }
+                Ok(message) => state.update(|state| state.apply(message)),
+                Err(e) => {
+                    error!("Failed to parse events message: {}", e);
                 }
             }
         }) as Box<dyn FnMut(MessageEvent)>);
         ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
         onmessage_callback.forget();
 
-        // Handle WebSocket close
-        let connection_state_close = self.connection_state;
+        let client = self.clone();
         let onclose_callback = Closure::wrap(Box::new(move |e: CloseEvent| {
-            warn!("WebSocket closed: {} - {}", e.code(), e.reason());
-            connection_state_close.set(ConnectionState::Disconnected);
-            // TODO: Implement auto-reconnection logic
+            warn!("Events WebSocket closed: {} - {}", e.code(), e.reason());
+            let mut inner = client.inner.borrow_mut();
+            inner.websocket = None;
+            if inner.closed {
+                client.connection_state.set(ConnectionState::Disconnected);
+                return;
+            }
+            let delay = inner.reconnect_delay.map_or(RECONNECT_DELAY, |delay| (delay * 2).min(MAX_RECONNECT_DELAY));
+            inner.reconnect_delay = Some(delay);
+            drop(inner);
+            client.connection_state.set(ConnectionState::Reconnecting);
+
+            let client = client.clone();
+            set_timeout(move || {
+                if client.inner.borrow().closed {
+                    return;
+                }
+                if let Err(e) = client.open() {
+                    error!("Failed to reopen events socket: {:?}", e);
+                    client.connection_state.set(ConnectionState::Error("Cannot reconnect".to_string()));
+                }
+            }, delay);
         }) as Box<dyn FnMut(CloseEvent)>);
         ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
         onclose_callback.forget();
 
-        // Handle WebSocket errors
         let connection_state_error = self.connection_state;
         let onerror_callback = Closure::wrap(Box::new(move |_: Event| {
-            error!("WebSocket error occurred");
+            error!("Events WebSocket error occurred");
             connection_state_error.set(ConnectionState::Error("Connection error".to_string()));
         }) as Box<dyn FnMut(Event)>);
         ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
         onerror_callback.forget();
 
-        self.websocket = Some(ws);
-        Ok(())
-    }
-
-    pub fn disconnect(&mut self) {
-        if let Some(ws) = &self.websocket {
-            let _ = ws.close();
-        }
-        self.websocket = None;
-        self.connection_state.set(ConnectionState::Disconnected);
-    }
-
-    pub fn send_message(&self, message: &RealtimeMessage) -> Result<(), JsValue> {
-        if let Some(ws) = &self.websocket {
-            let json = serde_json::to_string(message).map_err(|e| {
-                JsValue::from_str(&format!("Serialization error: {}", e))
-            })?;
-            ws.send_with_str(&json)
-        } else {
-            Err(JsValue::from_str("WebSocket not connected"))
+        let mut inner = self.inner.borrow_mut();
+        inner.websocket = Some(ws);
+        let start_keepalive = !std::mem::replace(&mut inner.keepalive_started, true);
+        drop(inner);
+        if start_keepalive {
+            self.start_keepalive();
         }
+        Ok(())
     }
 
-    pub fn set_message_handler<F>(&self, handler: F)
-    where
-        F: Fn(RealtimeMessage) + 'static,
-    {
-        self.message_handler.set(Some(Box::new(handler)));
+    /// Ping while connected, so the server does not close the socket as stale
+    fn start_keepalive(&self) {
+        let client = self.clone();
+        set_interval(move || {
+            let inner = client.inner.borrow();
+            if let Some(ws) = inner.websocket.as_ref().filter(|ws| ws.ready_state() == WebSocket::OPEN) {
+                let _ = ws.send_with_str(r#"{"type":"ping"}"#);
+            }
+        }, PING_INTERVAL);
     }
-}
 
-/// Real-time dashboard updates component
-#[component]
-pub fn RealtimeDashboard() -> impl IntoView {
-    let (realtime_client, set_realtime_client) = create_signal(RealtimeClient::new());
-    let (notifications, set_notifications) = create_signal(Vec::<RealtimeMessage>::new());
-    let (system_stats, set_system_stats) = create_signal(None::<SystemStats>);
-    let (device_updates, set_device_updates) = create_signal(HashMap::<String, DeviceStatus>::new());
-
-    #[derive(Clone, Debug)]
-    struct SystemStats {
-        connected_devices: usize,
-        active_sessions: usize,
-        bandwidth_usage: f64,
-        cpu_usage: f64,
+    /// Narrow the events to `subscription`; the server answers with a new
+    /// snapshot
+    pub fn subscribe(&self, subscription: LiveSubscription) {
+        self.inner.borrow_mut().subscription = subscription.clone();
+        self.send_subscription(&subscription);
     }
 
-    #[derive(Clone, Debug)]
-    struct DeviceStatus {
-        device_id: String,
-        is_online: bool,
-        last_seen: String,
+    fn send_subscription(&self, subscription: &LiveSubscription) {
+        let inner = self.inner.borrow();
+        let Some(ws) = inner.websocket.as_ref().filter(|ws| ws.ready_state() == WebSocket::OPEN) else { return };
+        let mut message = serde_json::to_value(subscription).unwrap_or_default();
+        message["type"] = "subscribe".into();
+        let _ = ws.send_with_str(&message.to_string());
     }
 
-    // Initialize WebSocket connection
-    create_effect(move |_| {
-        let mut client = realtime_client.get();
-
-        // Set up message handler
-        client.set_message_handler(move |message| {
-            match message {
-                RealtimeMessage::DeviceStatusUpdate { device_id, is_online, last_seen } => {
-                    set_device_updates.update(|updates| {
-                        updates.insert(device_id.clone(), DeviceStatus {
-                            device_id,
-                            is_online,
-                            last_seen,
-                        });
-                    });
-                }
-                RealtimeMessage::SystemStats { connected_devices, active_sessions, bandwidth_usage, cpu_usage } => {
-                    set_system_stats.set(Some(SystemStats {
-                        connected_devices,
-                        active_sessions,
-                        bandwidth_usage,
-                        cpu_usage,
-                    }));
-                }
-                RealtimeMessage::Notification { .. } => {
-                    set_notifications.update(|notifs| {
-                        notifs.push(message.clone());
-                        // Keep only last 10 notifications
-                        if notifs.len() > 10 {
-                            notifs.remove(0);
-                        }
-                    });
-                }
-                RealtimeMessage::DeviceConnected { device_id, device_name, platform } => {
-                    set_notifications.update(|notifs| {
-                        notifs.push(RealtimeMessage::Notification {
-                            level: "info".to_string(),
-                            title: "Device Connected".to_string(),
-                            message: format!("{} ({}) connected", device_name, platform),
-                            timestamp: chrono::Utc::now().to_rfc3339(),
-                        });
-                    });
-                }
-                RealtimeMessage::DeviceDisconnected { device_id } => {
-                    set_notifications.update(|notifs| {
-                        notifs.push(RealtimeMessage::Notification {
-                            level: "warning".to_string(),
-                            title: "Device Disconnected".to_string(),
-                            message: format!("Device {} disconnected", device_id),
-                            timestamp: chrono::Utc::now().to_rfc3339(),
-                        });
-                    });
-                }
-                _ => {}
-            }
-        });
-
-        // Connect to WebSocket
-        if let Err(e) = client.connect("ws://localhost:8080/ws/realtime") {
-            error!("Failed to connect to WebSocket: {:?}", e);
+    pub fn disconnect(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.closed = true;
+        if let Some(ws) = inner.websocket.take() {
+            let _ = ws.close();
         }
-
-        set_realtime_client.set(client);
-    });
-
-    view! {
-        <div class="realtime-dashboard">
-            // Connection status indicator
-            <div class="row mb-3">
-                <div class="col-12">
-                    <ConnectionStatus client=realtime_client />
-                </div>
-            </div>
-
-            // Real-time system stats
-            {move || {
-                if let Some(stats) = system_stats.get() {
-                    view! {
-                        <div class="row g-3 mb-4">
-                            <div class="col-md-3">
-                                <div class="card bg-primary text-white">
-                                    <div class="card-body">
-                                        <div class="d-flex align-items-center">
-                                            <i class="bi bi-hdd-network fs-1 me-3"></i>
-                                            <div>
-                                                <div class="fs-4 fw-bold">{stats.connected_devices}</div>
-                                                <div class="small">"Devices Online"</div>
-                                            </div>
-                                        </div>
-                                    </div>
-                                </div>
-                            </div>
-                            <div class="col-md-3">
-                                <div class="card bg-success text-white">
-                                    <div class="card-body">
-                                        <div class="d-flex align-items-center">
-                                            <i class="bi bi-display fs-1 me-3"></i>
-                                            <div>
-                                                <div class="fs-4 fw-bold">{stats.active_sessions}</div>
-                                                <div class="small">"Active Sessions"</div>
-                                            </div>
-                                        </div>
-                                    </div>
-                                </div>
-                            </div>
-                            <div class="col-md-3">
-                                <div class="card bg-info text-white">
-                                    <div class="card-body">
-                                        <div class="d-flex align-items-center">
-                                            <i class="bi bi-speedometer2 fs-1 me-3"></i>
-                                            <div>
-                                                <div class="fs-4 fw-bold">{format!("{:.1}%", stats.cpu_usage)}</div>
-                                                <div class="small">"CPU Usage"</div>
-                                            </div>
-                                        </div>
-                                    </div>
-                                </div>
-                            </div>
-                            <div class="col-md-3">
-                                <div class="card bg-warning text-dark">
-                                    <div class="card-body">
-                                        <div class="d-flex align-items-center">
-                                            <i class="bi bi-wifi fs-1 me-3"></i>
-                                            <div>
-                                                <div class="fs-4 fw-bold">{format!("{:.1} MB/s", stats.bandwidth_usage)}</div>
-                                                <div class="small">"Bandwidth"</div>
-                                            </div>
-                                        </div>
-                                    </div>
-                                </div>
-                            </div>
-                        </div>
-                    }.into_view()
-                } else {
-                    view! {
-                        <div class="row mb-4">
-                            <div class="col-12">
-                                <div class="card">
-                                    <div class="card-body text-center">
-                                        <div class="spinner-border text-primary me-2" role="status"></div>
-                                        "Loading real-time stats..."
-                                    </div>
-                                </div>
-                            </div>
-                        </div>
-                    }.into_view()
-                }
-            }}
-
-            // Real-time notifications
-            <div class="row">
-                <div class="col-12">
-                    <NotificationPanel notifications=notifications />
-                </div>
-            </div>
-        </div>
+        self.connection_state.set(ConnectionState::Disconnected);
     }
 }
 
-#[component]
-fn ConnectionStatus(client: ReadSignal<RealtimeClient>) -> impl IntoView {
-    let connection_state = create_memo(move |_| {
-        client.with(|c| c.connection_state().get())
-    });
-
-    view! {
-        <div class="connection-status">
-            {move || {
-                match connection_state.get() {
-                    ConnectionState::Connected => {
-                        view! {
-                            <div class="alert alert-success d-flex align-items-center" role="alert">
-                                <i class="bi bi-wifi me-2"></i>
-                                <span>"Real-time updates connected"</span>
-                                <div class="ms-auto">
-                                    <span class="badge bg-success">
-                                        <i class="bi bi-circle-fill me-1" style="font-size: 0.5rem;"></i>
-                                        "LIVE"
-                                    </span>
-                                </div>
-                            </div>
-                        }.into_view()
-                    }
-                    ConnectionState::Connecting => {
-                        view! {
-                            <div class="alert alert-info d-flex align-items-center" role="alert">
-                                <div class="spinner-border spinner-border-sm me-2" role="status"></div>
-                                <span>"Connecting to real-time updates..."</span>
-                            </div>
-                        }.into_view()
-                    }
-                    ConnectionState::Reconnecting => {
-                        view! {
-                            <div class="alert alert-warning d-flex align-items-center" role="alert">
-                                <div class="spinner-border spinner-border-sm me-2" role="status"></div>
-                                <span>"Reconnecting to real-time updates..."</span>
-                            </div>
-                        }.into_view()
-                    }
-                    ConnectionState::Disconnected => {
-                        view! {
-                            <div class="alert alert-secondary d-flex align-items-center" role="alert">
-                                <i class="bi bi-wifi-off me-2"></i>
-                                <span>"Real-time updates disconnected"</span>
-                            </div>
-                        }.into_view()
-                    }
-                    ConnectionState::Error(msg) => {
-                        view! {
-                            <div class="alert alert-danger d-flex align-items-center" role="alert">
-                                <i class="bi bi-exclamation-triangle me-2"></i>
-                                <span>{format!("Connection error: {}", msg)}</span>
-                            </div>
-                        }.into_view()
-                    }
-                }
-            }}
-        </div>
-    }
+/// `/api/ws/events` on this server, with the signed-in user's token since
+/// browsers cannot set headers on a WebSocket
+fn events_url() -> Result<String, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window available"))?;
+    let location = window.location();
+    let protocol = if location.protocol().unwrap_or_default() == "https:" { "wss:" } else { "ws:" };
+    let host = location.host()?;
+    let token = window.local_storage().ok().flatten()
+        .and_then(|storage| storage.get_item("access_token").ok().flatten());
+
+    Ok(match token {
+        Some(token) => format!("{}//{}/api/ws/events?access_token={}", protocol, host, js_sys::encode_uri_component(&token)),
+        None => format!("{}//{}/api/ws/events", protocol, host),
+    })
 }
 
+/// Badge showing whether live updates are flowing
 #[component]
-fn NotificationPanel(notifications: ReadSignal<Vec<RealtimeMessage>>) -> impl IntoView {
+pub fn ConnectionStatus(state: ReadSignal<ConnectionState>) -> impl IntoView {
     view! {
-        <div class="card">
-            <div class="card-header">
-                <h6 class="mb-0">
-                    <i class="bi bi-bell me-2"></i>
-                    "Real-time Notifications"
-                </h6>
-            </div>
-            <div class="card-body p-0">
-                <div class="list-group list-group-flush" style="max-height: 300px; overflow-y: auto;">
-                    <For
-                        each=move || notifications.get()
-                        key=|notification| {
-                            if let RealtimeMessage::Notification { timestamp, .. } = notification {
-                                timestamp.clone()
-                            } else {
-                                chrono::Utc::now().to_rfc3339()
-                            }
-                        }
-                        children=move |notification| {
-                            if let RealtimeMessage::Notification { level, title, message, timestamp } = notification {
-                                let (icon, class) = match level.as_str() {
-                                    "error" => ("bi-exclamation-triangle-fill", "text-danger"),
-                                    "warning" => ("bi-exclamation-triangle", "text-warning"),
-                                    "info" => ("bi-info-circle", "text-info"),
-                                    _ => ("bi-info-circle", "text-secondary"),
-                                };
-
-                                view! {
-                                    <div class="list-group-item">
-                                        <div class="d-flex align-items-start">
-                                            <i class={format!("bi {} {} me-2 mt-1", icon, class)}></i>
-                                            <div class="flex-grow-1">
-                                                <div class="fw-bold small">{title}</div>
-                                                <div class="text-muted small">{message}</div>
-                                            </div>
-                                            <small class="text-muted">{format_timestamp(&timestamp)}</small>
-                                        </div>
-                                    </div>
-                                }.into_view()
-                            } else {
-                                view! {}.into_view()
-                            }
-                        }
-                    />
-                </div>
-            </div>
-        </div>
+        {move || {
+            let (class, icon, label) = match state.get() {
+                ConnectionState::Connected => ("badge bg-success", "bi-circle-fill", "Live".to_string()),
+                ConnectionState::Connecting => ("badge bg-info", "bi-hourglass-split", "Connecting".to_string()),
+                ConnectionState::Reconnecting => ("badge bg-warning text-dark", "bi-arrow-repeat", "Reconnecting".to_string()),
+                ConnectionState::Disconnected => ("badge bg-secondary", "bi-wifi-off", "Offline".to_string()),
+                ConnectionState::Error(message) => ("badge bg-danger", "bi-exclamation-triangle", message),
+            };
+            view! {
+                <span class=class title="Live updates">
+                    <i class=format!("bi {} me-1", icon)></i>
+                    {label}
+                </span>
+            }
+        }}
     }
 }
 
-fn format_timestamp(timestamp: &str) -> String {
-    // Simple timestamp formatting - in production you'd use a proper date library
-    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(timestamp) {
-        dt.format("%H:%M:%S").to_string()
-    } else {
-        "N/A".to_string()
-    }
-}
\ No newline at end of file
+fn set_interval<F>(f: F, delay: Duration)
+where
+    F: Fn() + 'static,
+{
+    let closure = Closure::wrap(Box::new(f) as Box<dyn Fn()>);
+
+    web_sys::window()
+        .unwrap()
+        .set_interval_with_callback_and_timeout_and_arguments_0(
+            closure.as_ref().unchecked_ref(),
+            delay.as_millis() as i32,
+        )
+        .expect("should register interval");
+
+    closure.forget();
+}
+
+fn set_timeout<F>(f: F, delay: Duration)
+where
+    F: FnOnce() + 'static,
+{
+    let closure = Closure::once(f);
+
+    web_sys::window()
+        .unwrap()
+        .set_timeout_with_callback_and_timeout_and_arguments_0(
+            closure.as_ref().unchecked_ref(),
+            delay.as_millis() as i32,
+        )
+        .expect("should register timeout");
+
+    closure.forget();
+}
@@ -4,6 +4,8 @@ use web_sys::*;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::web::api_client::{AgentCapabilities, AgentCapability};
+
 /// Session launcher that detects and launches native client
 #[component]
 pub fn SessionLauncher() -> impl IntoView {
@@ -97,6 +99,11 @@ pub fn DeviceCardWithLauncher(device: Device) -> impl IntoView {
         });
     });
 
+    // Only offer what the agent said it can do
+    let can_control = device.capabilities.supports(AgentCapability::InputControl);
+    let can_view = device.capabilities.supports(AgentCapability::ScreenCapture);
+    let can_transfer = device.capabilities.supports(AgentCapability::FileTransfer);
+
     let launch_session = move |session_type: SessionType| {
         let device_id = device.id.clone();
         spawn_local(async move {
@@ -143,7 +150,7 @@ pub fn DeviceCardWithLauncher(device: Device) -> impl IntoView {
                                     <div class="btn-group" role="group">
                                         <button
                                             class="btn btn-primary"
-                                            disabled=move || !device.is_online || launching_session.get()
+                                            disabled=move || !device.is_online || !can_view || !can_control || launching_session.get()
                                             on:click={
                                                 let launch = launch_session.clone();
                                                 move |_| launch(SessionType::Control)
@@ -167,7 +174,7 @@ pub fn DeviceCardWithLauncher(device: Device) -> impl IntoView {
                                         </button>
                                         <button
                                             class="btn btn-outline-primary"
-                                            disabled=move || !device.is_online
+                                            disabled=move || !device.is_online || !can_view
                                             on:click={
                                                 let launch = launch_session.clone();
                                                 move |_| launch(SessionType::View)
@@ -177,7 +184,7 @@ pub fn DeviceCardWithLauncher(device: Device) -> impl IntoView {
                                         </button>
                                         <button
                                             class="btn btn-outline-primary"
-                                            disabled=move || !device.is_online
+                                            disabled=move || !device.is_online || !can_transfer
                                             on:click={
                                                 let launch = launch_session.clone();
                                                 move |_| launch(SessionType::FileTransfer)
@@ -355,6 +362,7 @@ pub struct Device {
     pub hostname: String,
     pub platform: String,
     pub is_online: bool,
+    pub capabilities: AgentCapabilities,
 }
 
 // Component stubs - you'd implement these
@@ -1,9 +1,18 @@
 use leptos::*;
 use leptos_router::*;
 use crate::web::api_client::*;
+use crate::relay::input_coalescer::{self, Flush, InputCoalescer};
+use crate::relay::input_mode::InputMode;
+use crate::web::stream::{
+    coalesce_input, key_code, mouse_button, parse_frame, viewer_resolution_batch, DecodeStats, InputEvent,
+    MovementAccumulator, StatsSnapshot, StreamConfig, Viewport, WheelAccumulator,
+};
+use crate::web::video::VideoDecoder;
 use web_sys::WebSocket;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::closure::Closure;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::time::Duration;
 
 #[component]
@@ -297,6 +306,7 @@ fn SessionRow(session: Session, devices: Vec<Device>) -> impl IntoView {
         SessionType::Control => "bi-joystick",
         SessionType::FileTransfer => "bi-folder",
         SessionType::Terminal => "bi-terminal",
+        SessionType::Backstage => "bi-incognito",
     };
 
     let session_type_color = match session.session_type {
@@ -304,6 +314,7 @@ fn SessionRow(session: Session, devices: Vec<Device>) -> impl IntoView {
         SessionType::Control => "text-warning",
         SessionType::FileTransfer => "text-success",
         SessionType::Terminal => "text-primary",
+        SessionType::Backstage => "text-secondary",
     };
 
     let status_badge = match session.status {
@@ -369,6 +380,11 @@ fn SessionRow(session: Session, devices: Vec<Device>) -> impl IntoView {
                     <i class={format!("bi {} {} me-2", session_type_icon, session_type_color)}></i>
                     <span class="text-capitalize">{format!("{:?}", session.session_type)}</span>
                 </div>
+                {session.ticket_ref.clone().map(|ticket_ref| view! {
+                    <small class="text-muted" title="Ticket">
+                        <i class="bi bi-ticket me-1"></i>{ticket_ref}
+                    </small>
+                })}
             </td>
             <td>
                 <span class={format!("badge {}", status_badge.0)}>{status_badge.1}</span>
@@ -420,17 +436,165 @@ fn SessionRow(session: Session, devices: Vec<Device>) -> impl IntoView {
 #[component]
 pub fn SessionViewer() -> impl IntoView {
     let params = use_params_map();
+    let query = use_query_map();
     let session_id = move || params.with(|p| p.get("id").cloned().unwrap_or_default());
     
     let (ws, set_ws) = create_signal(None::<WebSocket>);
     let (connected, set_connected) = create_signal(false);
     let (error, set_error) = create_signal(None::<String>);
+    let (quality, set_quality) = create_signal(None::<ConnectionQuality>);
+    let (stream, set_stream) = create_signal(None::<StreamConfig>);
+    let (decode_error, set_decode_error) = create_signal(None::<String>);
+    let (stats, set_stats) = create_signal(StatsSnapshot::default());
+    let (has_control, set_has_control) = create_signal(false);
+    let (view_only, set_view_only) = create_signal(false);
+    // The mode the agent confirmed, whether this page holds the pointer lock,
+    // and the agent's last suggestion or refusal
+    let (input_mode, set_input_mode) = create_signal(InputMode::Absolute);
+    let (pointer_locked, set_pointer_locked) = create_signal(false);
+    let (mode_hint, set_mode_hint) = create_signal(None::<(InputMode, String)>);
+    // Whether the agent confirmed preview mode, and how many events it has
+    // held back since
+    let (input_preview, set_input_preview) = create_signal(false);
+    let (previewed, set_previewed) = create_signal(0u64);
+    // Whether this page only watches alongside the technician, and the
+    // latest `SessionTransfer` of the session
+    let (observing, set_observing) = create_signal(false);
+    let (transfer, set_transfer) = create_signal(None::<serde_json::Value>);
+    // Bandwidth cap in force as the relay confirmed it, and whether the
+    // low bandwidth preset was picked here
+    let (bandwidth_cap, set_bandwidth_cap) = create_signal(None::<u64>);
+    // Parts of the session the agent's watchdog is restarting, from
+    // `SessionHealth` and each quality report
+    let (recovering, set_recovering) = create_signal(Vec::<String>::new());
+    let (low_bandwidth, set_low_bandwidth) = create_signal(false);
+    // File panel: listings come over REST, renames and deletions over the
+    // socket; the directories opened so far, innermost last
+    let (files_open, set_files_open) = create_signal(false);
+    let (listing, set_listing) = create_signal(None::<DirectoryListing>);
+    let (files_error, set_files_error) = create_signal(None::<String>);
+    let folders = store_value(Vec::<String>::new());
+    // Files dropped or pasted onto the screen: the latest `FileDropProgress`
+    // of the upload in flight, or why it was refused
+    let (drop_progress, set_drop_progress) = create_signal(None::<serde_json::Value>);
+    let (drop_error, set_drop_error) = create_signal(None::<String>);
+
+    let canvas_ref = create_node_ref::<html::Canvas>();
+    let decoder = Rc::new(RefCell::new(None::<VideoDecoder>));
+    let decode_stats = Rc::new(RefCell::new(DecodeStats::default()));
+    let wheel = RefCell::new(WheelAccumulator::default());
+    let movement = store_value(MovementAccumulator::default());
+
+    let send_text = move |message: serde_json::Value| {
+        ws.with_untracked(|ws| {
+            if let Some(ws) = ws {
+                let _ = ws.send_with_str(&message.to_string());
+            }
+        });
+    };
+    let current_folder = move || folders.with_value(|folders| folders.last().cloned().unwrap_or_default());
+    let load_directory = move |path: String, offset: usize| {
+        let id = params.with_untracked(|p| p.get("id").cloned().unwrap_or_default());
+        spawn_local(async move {
+            match ApiClient::list_directory(&id, &path, offset).await {
+                Ok(page) if offset > 0 => set_listing.update(|listing| {
+                    if let Some(listing) = listing {
+                        listing.entries.extend(page.entries);
+                        listing.next_offset = page.next_offset;
+                    }
+                }),
+                Ok(page) => {
+                    set_files_error.set(None);
+                    set_listing.set(Some(page));
+                }
+                Err(e) => set_files_error.set(Some(e)),
+            }
+        });
+    };
+    let open_folder = move |path: String| {
+        folders.update_value(|folders| folders.push(path.clone()));
+        load_directory(path, 0);
+    };
+    let folder_up = move || {
+        folders.update_value(|folders| {
+            folders.pop();
+        });
+        load_directory(current_folder(), 0);
+    };
+    let rename_entry = move |entry: FsEntry| {
+        let Ok(Some(name)) = window().prompt_with_message_and_default("Rename to", &entry.name) else {
+            return;
+        };
+        if name.is_empty() || name == entry.name {
+            return;
+        }
+        let parent = &entry.path[..entry.path.len() - entry.name.len()];
+        send_text(serde_json::json!({
+            "type": "Rename",
+            "request_id": js_sys::Date::now().to_string(),
+            "from": entry.path,
+            "to": format!("{}{}", parent, name),
+        }));
+    };
+    let delete_entry = move |entry: FsEntry| {
+        if !window().confirm_with_message(&format!("Delete {}?", entry.path)).unwrap_or(false) {
+            return;
+        }
+        send_text(serde_json::json!({
+            "type": "Delete",
+            "request_id": js_sys::Date::now().to_string(),
+            "path": entry.path,
+            "recursive": entry.is_dir && !entry.is_symlink,
+        }));
+    };
+    let upload_files = move |files: web_sys::FileList| {
+        if files.length() == 0 || view_only.get_untracked() {
+            return;
+        }
+        let Ok(form) = web_sys::FormData::new() else {
+            return;
+        };
+        for file in (0..files.length()).filter_map(|index| files.get(index)) {
+            let _ = form.append_with_blob_and_filename("files", &file, &file.name());
+        }
+        let id = params.with_untracked(|p| p.get("id").cloned().unwrap_or_default());
+        set_drop_error.set(None);
+        spawn_local(async move {
+            if let Err(e) = ApiClient::upload_files(&id, &form).await {
+                set_drop_error.set(Some(e));
+            }
+        });
+    };
+    // Pointer motion is coalesced before it goes on the socket; buttons and
+    // keys go out at once, behind it
+    let input = store_value(InputCoalescer::new(input_coalescer::DEFAULT_WINDOW));
+    let flush_input = move || {
+        if let Some(batch) = input.try_update_value(|input| input.flush()).flatten() {
+            ws.with_untracked(|ws| {
+                if let Some(ws) = ws {
+                    let _ = ws.send_with_u8_array(&batch);
+                }
+            });
+        }
+    };
+    let send_input = move |events: &[InputEvent]| {
+        if !has_control.get_untracked() || view_only.get_untracked() {
+            return;
+        }
+        match input.try_update_value(|input| coalesce_input(input, events)) {
+            Some(Flush::Now) => flush_input(),
+            Some(Flush::After(window)) => set_timeout(flush_input, window),
+            _ => {}
+        }
+    };
 
     // Initialize WebSocket connection
+    let interval_stats = decode_stats.clone();
     create_effect(move |_| {
         let id = session_id();
+        let token = query.with_untracked(|q| q.get("token").cloned().unwrap_or_default());
         if !id.is_empty() {
-            match create_session_websocket(&id) {
+            match create_session_websocket(&id, &token) {
                 Ok(websocket) => {
                     // Set up WebSocket event handlers
                     let onopen_callback = Closure::wrap(Box::new(move |_| {
@@ -448,14 +612,170 @@ pub fn SessionViewer() -> impl IntoView {
                         logging::log!("WebSocket connection closed");
                     }) as Box<dyn FnMut(web_sys::CloseEvent)>);
 
+                    // Text messages are relay notices, binary ones screen frames
+                    let decoder = decoder.clone();
+                    let decode_stats = decode_stats.clone();
+                    let session = session_id();
+                    let onmessage_callback = Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
+                        if let Some(text) = e.data().as_string() {
+                            if let Some(report) = ConnectionQuality::from_message(&text) {
+                                set_recovering.set(report.recovering.clone());
+                                set_quality.set(Some(report));
+                            } else if let Some(config) = StreamConfig::from_message(&text) {
+                                let Some(canvas) = canvas_ref.get_untracked() else {
+                                    return;
+                                };
+                                let mut decoder = decoder.borrow_mut();
+                                let decoder = decoder.get_or_insert_with(|| {
+                                    let decode_stats = decode_stats.clone();
+                                    VideoDecoder::new(
+                                        &canvas,
+                                        move || decode_stats.borrow_mut().frame_decoded(js_sys::Date::now()),
+                                        move |reason| set_decode_error.set(Some(reason)),
+                                    )
+                                });
+                                set_decode_error.set(None);
+                                if decoder.configure(config.clone()) {
+                                    // Pointer positions are sent in stream pixels
+                                    ws.with_untracked(|ws| {
+                                        if let Some(ws) = ws {
+                                            let _ = ws.send_with_u8_array(&viewer_resolution_batch(config.width, config.height));
+                                        }
+                                    });
+                                    set_stream.set(Some(config));
+                                } else {
+                                    let reason = format!("This browser cannot decode {}", config.codec.name());
+                                    send_text(serde_json::json!({ "type": "decode_failed", "reason": reason }));
+                                }
+                            } else if let Ok(message) = serde_json::from_str::<serde_json::Value>(&text) {
+                                match message.get("type").and_then(|t| t.as_str()) {
+                                    Some("ControlChanged") => {
+                                        let holder = message.get("holder").and_then(|h| h.as_str());
+                                        let ours = holder == Some(session.as_str()) && !observing.get_untracked();
+                                        set_has_control.set(ours);
+                                        if !ours && pointer_locked.get_untracked() {
+                                            document().exit_pointer_lock();
+                                        } else if ours && !pointer_locked.get_untracked() {
+                                            // The previous holder may have left the session relative
+                                            send_text(serde_json::json!({ "type": "set_input_mode", "mode": InputMode::Absolute }));
+                                        }
+                                    }
+                                    Some("InputModeChanged") => {
+                                        let mode = message.get("mode").and_then(|m| m.as_str())
+                                            .and_then(|m| m.parse::<InputMode>().ok()).unwrap_or_default();
+                                        let reason = message.get("reason").and_then(|r| r.as_str());
+                                        set_input_mode.set(mode);
+                                        set_mode_hint.set(reason.map(|reason| (mode, reason.to_string())));
+                                        if mode == InputMode::Absolute && pointer_locked.get_untracked() {
+                                            // The agent refused or dropped relative input
+                                            document().exit_pointer_lock();
+                                        } else if mode == InputMode::Relative && !pointer_locked.get_untracked() {
+                                            // The lock ended while the switch was in flight
+                                            send_text(serde_json::json!({ "type": "set_input_mode", "mode": InputMode::Absolute }));
+                                        }
+                                    }
+                                    Some("InputModeHint") => {
+                                        let mode = message.get("mode").and_then(|m| m.as_str())
+                                            .and_then(|m| m.parse::<InputMode>().ok());
+                                        let reason = message.get("reason").and_then(|r| r.as_str()).unwrap_or_default();
+                                        if let Some(mode) = mode.filter(|mode| *mode != input_mode.get_untracked()) {
+                                            set_mode_hint.set(Some((mode, reason.to_string())));
+                                        }
+                                    }
+                                    Some("ViewerRole") => {
+                                        let observer = message.get("role").and_then(|r| r.as_str()) == Some("observer");
+                                        set_observing.set(observer);
+                                        if observer {
+                                            set_has_control.set(false);
+                                        }
+                                    }
+                                    Some("SessionTransfer") => {
+                                        set_transfer.set(message.get("transfer").cloned());
+                                    }
+                                    Some("InputPreviewChanged") => {
+                                        set_input_preview.set(message.get("enabled").and_then(|e| e.as_bool()).unwrap_or(false));
+                                        set_previewed.set(0);
+                                    }
+                                    Some("InputPreviewed") => {
+                                        set_previewed.update(|count| *count += 1);
+                                    }
+                                    Some("BandwidthCapChanged") => {
+                                        set_bandwidth_cap.set(message.get("kbps").and_then(|k| k.as_u64()));
+                                    }
+                                    Some("FsResult") => {
+                                        if message.get("success").and_then(|s| s.as_bool()) == Some(true) {
+                                            load_directory(current_folder(), 0);
+                                        } else {
+                                            set_files_error.set(Some(describe_fs_error(&message["error"])));
+                                        }
+                                    }
+                                    Some("FileDropProgress") => {
+                                        let done = message.get("done").and_then(|d| d.as_bool()) == Some(true);
+                                        let upload_id = message.get("upload_id").cloned();
+                                        set_drop_progress.set(Some(message));
+                                        if done {
+                                            // Leave the outcome up for a moment, unless another drop took its place
+                                            set_timeout(move || set_drop_progress.update(|progress| {
+                                                if progress.as_ref().map(|p| p.get("upload_id").cloned()) == Some(upload_id) {
+                                                    *progress = None;
+                                                }
+                                            }), DROP_STATUS_LINGER);
+                                        }
+                                    }
+                                    Some("ScopeDenied") if message.get("scope").and_then(|s| s.as_str()) == Some("control") => {
+                                        set_view_only.set(true);
+                                    }
+                                    Some("SessionCancelled") => {
+                                        set_error.set(Some("The session was cancelled".to_string()));
+                                    }
+                                    Some("SessionFailed") => {
+                                        set_error.set(Some(describe_session_failure(&message)));
+                                    }
+                                    Some("SessionHealth") => {
+                                        let component = message.get("component").and_then(|c| c.as_str()).unwrap_or_default().to_string();
+                                        let restarting = message.get("state").and_then(|s| s.as_str()) == Some("recovering");
+                                        set_recovering.update(|recovering| {
+                                            recovering.retain(|c| *c != component);
+                                            if restarting {
+                                                recovering.push(component);
+                                            }
+                                        });
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        } else if let Ok(buffer) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                            let data = js_sys::Uint8Array::new(&buffer).to_vec();
+                            let mut decoder = decoder.borrow_mut();
+                            let Some(decoder) = decoder.as_mut() else {
+                                return;
+                            };
+                            let Some(config) = decoder.config().cloned() else {
+                                return;
+                            };
+                            let queued = match parse_frame(&data, &config) {
+                                Ok(frame) => decoder.decode(&frame),
+                                Err(e) => {
+                                    logging::log!("Dropping screen frame: {}", e);
+                                    false
+                                }
+                            };
+                            if !queued {
+                                decode_stats.borrow_mut().frame_dropped();
+                            }
+                        }
+                    }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+
                     websocket.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
                     websocket.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
                     websocket.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+                    websocket.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
 
                     // Prevent closures from being dropped
                     onopen_callback.forget();
                     onerror_callback.forget();
                     onclose_callback.forget();
+                    onmessage_callback.forget();
 
                     set_ws.set(Some(websocket));
                 }
@@ -466,6 +786,95 @@ pub fn SessionViewer() -> impl IntoView {
         }
     });
 
+    set_interval(move || set_stats.set(interval_stats.borrow_mut().snapshot(js_sys::Date::now())), Duration::from_secs(1));
+
+    // The browser grants and ends the pointer lock (Escape always ends it);
+    // follow it and ask the agent for the matching input mode
+    let on_pointer_lock_change = Closure::wrap(Box::new(move |_: web_sys::Event| {
+        let locked = document().pointer_lock_element().is_some();
+        set_pointer_locked.set(locked);
+        movement.update_value(MovementAccumulator::reset);
+        let wanted = if locked { InputMode::Relative } else { InputMode::Absolute };
+        if wanted != input_mode.get_untracked() && has_control.get_untracked() {
+            send_text(serde_json::json!({ "type": "set_input_mode", "mode": wanted }));
+        }
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    let _ = document().add_event_listener_with_callback("pointerlockchange", on_pointer_lock_change.as_ref().unchecked_ref());
+    on_pointer_lock_change.forget();
+    let request_pointer_lock = move || {
+        if let Some(canvas) = canvas_ref.get_untracked() {
+            set_mode_hint.set(None);
+            canvas.request_pointer_lock();
+        }
+    };
+
+    // Where the pointer is on the remote screen; the canvas backing store is
+    // the stream's size and CSS fits it into the element
+    let viewport = move || {
+        let canvas = canvas_ref.get_untracked()?;
+        Viewport::fit(canvas.client_width() as f64, canvas.client_height() as f64, canvas.width(), canvas.height())
+    };
+    let on_mouse_move = move |e: ev::MouseEvent| {
+        if pointer_locked.get_untracked() {
+            // Movement only counts once the agent applies it as relative
+            if input_mode.get_untracked() == InputMode::Relative {
+                let scale = window().device_pixel_ratio();
+                let delta = movement.try_update_value(|movement| {
+                    movement.add(e.movement_x() as f64 * scale, e.movement_y() as f64 * scale)
+                });
+                if let Some(delta) = delta.flatten() {
+                    send_input(&[delta]);
+                }
+            }
+            return;
+        }
+        if let Some((x, y)) = viewport().and_then(|v| v.to_stream(e.offset_x() as f64, e.offset_y() as f64)) {
+            send_input(&[InputEvent::MouseMove { x, y }]);
+        }
+    };
+    let on_mouse_button = move |e: ev::MouseEvent, pressed: bool| {
+        let Some(button) = mouse_button(e.button()) else {
+            return;
+        };
+        if pointer_locked.get_untracked() {
+            // A locked pointer has no position, so buttons press where it is
+            e.prevent_default();
+            send_input(&[InputEvent::MouseButton { button, pressed }]);
+            return;
+        }
+        let Some(viewport) = viewport() else {
+            return;
+        };
+        e.prevent_default();
+        let position = if pressed {
+            let Some(position) = viewport.to_stream(e.offset_x() as f64, e.offset_y() as f64) else {
+                return;
+            };
+            if let Some(canvas) = canvas_ref.get_untracked() {
+                let _ = canvas.focus();
+            }
+            position
+        } else {
+            viewport.clamp_to_stream(e.offset_x() as f64, e.offset_y() as f64)
+        };
+        send_input(&[
+            InputEvent::MouseMove { x: position.0, y: position.1 },
+            InputEvent::MouseButton { button, pressed },
+        ]);
+    };
+    let on_wheel = move |e: ev::WheelEvent| {
+        e.prevent_default();
+        if let Some(scroll) = wheel.borrow_mut().add(e.delta_x(), e.delta_y(), e.delta_mode()) {
+            send_input(&[scroll]);
+        }
+    };
+    let on_key = move |e: ev::KeyboardEvent, pressed: bool| {
+        if let Some(key) = key_code(&e.code()) {
+            e.prevent_default();
+            send_input(&[InputEvent::KeyEvent { key, pressed }]);
+        }
+    };
+
     view! {
         <div class="session-viewer vh-100 d-flex flex-column">
             <div class="session-header bg-dark text-white p-3">
@@ -478,8 +887,160 @@ pub fn SessionViewer() -> impl IntoView {
                         <span class={if connected.get() { "badge bg-success" } else { "badge bg-danger" }}>
                             {if connected.get() { "Connected" } else { "Disconnected" }}
                         </span>
+                        {move || quality.get().map(|quality| view! {
+                            <span class={format!("badge ms-2 {}", quality.badge_class())} title={quality.details()}>
+                                <i class="bi bi-reception-4 me-1"></i>
+                                {format!("{:.0} ms", quality.rtt_ms)}
+                            </span>
+                        })}
+                        {move || describe_recovery(&recovering.get()).map(|text| view! {
+                            <span class="badge bg-warning text-dark ms-2" title="The agent is restarting part of the session">
+                                <span class="spinner-border spinner-border-sm me-1"></span>
+                                {text}
+                            </span>
+                        })}
+                        {move || quality.get().and_then(|quality| quality.throughput_kbps.zip(quality.bandwidth_cap_kbps)).map(|(throughput, cap)| view! {
+                            <span
+                                class={if throughput > cap { "badge bg-warning text-dark ms-2" } else { "badge bg-secondary ms-2" }}
+                                title="Measured throughput against the session's bandwidth cap"
+                            >
+                                {format!("{} / {} kbit/s", throughput, cap)}
+                            </span>
+                        })}
+                        {move || stream.get().map(|config| {
+                            let stats = stats.get();
+                            view! {
+                                <span class="badge bg-secondary ms-2" title={format!("{} decoded, {} dropped", stats.decoded, stats.dropped)}>
+                                    {format!("{} {}x{} · {:.0} fps · {} dropped", config.codec.name(), config.width, config.height, stats.fps, stats.dropped)}
+                                </span>
+                            }
+                        })}
                     </div>
                     <div class="d-flex gap-2">
+                        {move || decode_error.get().map(|reason| view! {
+                            <button
+                                class="btn btn-warning btn-sm"
+                                title={reason}
+                                on:click=move |_| {
+                                    set_decode_error.set(None);
+                                    send_text(serde_json::json!({ "type": "request_keyframe" }));
+                                }
+                            >
+                                <i class="bi bi-arrow-repeat me-1"></i>
+                                "Request keyframe"
+                            </button>
+                        })}
+                        {move || mode_hint.get().filter(|_| has_control.get()).map(|(mode, reason)| match mode {
+                            InputMode::Relative if !pointer_locked.get() => view! {
+                                <button class="btn btn-info btn-sm" title={reason} on:click=move |_| request_pointer_lock()>
+                                    <i class="bi bi-cursor me-1"></i>
+                                    "Switch to relative mouse"
+                                </button>
+                            }.into_view(),
+                            InputMode::Absolute if pointer_locked.get() => view! {
+                                <button class="btn btn-info btn-sm" title={reason} on:click=move |_| document().exit_pointer_lock()>
+                                    <i class="bi bi-cursor-fill me-1"></i>
+                                    "Switch to absolute mouse"
+                                </button>
+                            }.into_view(),
+                            _ => view! {
+                                <span class="badge bg-secondary align-self-center" title={reason}>
+                                    {format!("Mouse: {}", mode)}
+                                </span>
+                            }.into_view(),
+                        })}
+                        <select
+                            class="form-select form-select-sm w-auto"
+                            title="Cap the bandwidth the session may use"
+                            prop:value=move || bandwidth_cap.get().map(|kbps| kbps.to_string()).unwrap_or_default()
+                            on:change=move |e| {
+                                let kbps = event_target_value(&e).parse::<u64>().ok();
+                                send_text(serde_json::json!({ "type": "set_bandwidth", "kbps": kbps }));
+                            }
+                        >
+                            <option value="">"No bandwidth cap"</option>
+                            {BANDWIDTH_CAPS.iter().map(|kbps| view! {
+                                <option value={kbps.to_string()}>{format!("{} kbit/s", kbps)}</option>
+                            }).collect_view()}
+                            // A policy ceiling may land between the presets
+                            {move || bandwidth_cap.get().filter(|kbps| !BANDWIDTH_CAPS.contains(kbps)).map(|kbps| view! {
+                                <option value={kbps.to_string()}>{format!("{} kbit/s (policy)", kbps)}</option>
+                            })}
+                        </select>
+                        <button
+                            class={move || if low_bandwidth.get() { "btn btn-light btn-sm" } else { "btn btn-outline-light btn-sm" }}
+                            title="256 colors at 5 fps, for metered or cellular links"
+                            on:click=move |_| {
+                                let enable = !low_bandwidth.get_untracked();
+                                set_low_bandwidth.set(enable);
+                                let (profile, quality) = if enable { ("low-bandwidth", 0) } else { ("balanced", 80) };
+                                send_text(serde_json::json!({ "type": "set_quality", "profile": profile, "quality": quality }));
+                            }
+                        >
+                            <i class="bi bi-speedometer me-1"></i>
+                            "Low bandwidth"
+                        </button>
+                        {move || has_control.get().then(|| view! {
+                            <button
+                                class={move || if input_preview.get() { "btn btn-warning btn-sm" } else { "btn btn-outline-light btn-sm" }}
+                                title="Send input to the agent without injecting it, to check what the remote machine would receive"
+                                on:click=move |_| send_text(serde_json::json!({ "type": "set_input_preview", "enabled": !input_preview.get_untracked() }))
+                            >
+                                <i class="bi bi-eye me-1"></i>
+                                {move || if input_preview.get() { format!("Previewing input ({})", previewed.get()) } else { "Preview input".to_string() }}
+                            </button>
+                        })}
+                        {move || (has_control.get() && !pointer_locked.get()).then(|| view! {
+                            <button class="btn btn-outline-light btn-sm" title="Lock the pointer and send relative movement; press Esc to leave" on:click=move |_| request_pointer_lock()>
+                                <i class="bi bi-cursor me-1"></i>
+                                "Relative mouse"
+                            </button>
+                        })}
+                        {move || transfer.get().filter(|t| t["state"] == "offered" || t["state"] == "accepted" || t["state"] == "shadowing").map(|t| {
+                            let shadowing = t["state"] == "shadowing";
+                            let to = t["to_name"].as_str().unwrap_or("a colleague").to_string();
+                            view! {
+                                <span class="badge bg-info align-self-center" title="Session transfer">
+                                    <i class="bi bi-people me-1"></i>
+                                    {if observing.get() { "Shadowing before you take over".to_string() } else { format!("Handing over to {}", to) }}
+                                </span>
+                                {shadowing.then(|| view! {
+                                    <button class="btn btn-info btn-sm" on:click=move |_| send_text(serde_json::json!({ "type": "complete_transfer" }))>
+                                        <i class="bi bi-arrow-left-right me-1"></i>
+                                        {move || if observing.get() { "Take over now" } else { "Hand over now" }}
+                                    </button>
+                                })}
+                            }
+                        })}
+                        {move || (!view_only.get() && !observing.get()).then(|| if has_control.get() {
+                            view! {
+                                <button class="btn btn-light btn-sm" on:click=move |_| send_text(serde_json::json!({ "type": "release_control" }))>
+                                    <i class="bi bi-mouse me-1"></i>
+                                    "Release control"
+                                </button>
+                            }
+                        } else {
+                            view! {
+                                <button class="btn btn-outline-light btn-sm" on:click=move |_| send_text(serde_json::json!({ "type": "request_control" }))>
+                                    <i class="bi bi-mouse me-1"></i>
+                                    "Take control"
+                                </button>
+                            }
+                        })}
+                        <button
+                            class={move || if files_open.get() { "btn btn-light btn-sm" } else { "btn btn-outline-light btn-sm" }}
+                            title="Browse the remote files"
+                            on:click=move |_| {
+                                let open = !files_open.get_untracked();
+                                set_files_open.set(open);
+                                if open && listing.get_untracked().is_none() {
+                                    load_directory(current_folder(), 0);
+                                }
+                            }
+                        >
+                            <i class="bi bi-folder2-open me-1"></i>
+                            "Files"
+                        </button>
                         <button class="btn btn-outline-light btn-sm">
                             <i class="bi bi-fullscreen"></i>
                         </button>
@@ -494,6 +1055,105 @@ pub fn SessionViewer() -> impl IntoView {
             </div>
             
             <div class="flex-grow-1 position-relative bg-dark">
+                <canvas
+                    node_ref=canvas_ref
+                    class="remote-screen"
+                    tabindex="0"
+                    on:mousemove=on_mouse_move
+                    on:mousedown=move |e| on_mouse_button(e, true)
+                    on:mouseup=move |e| on_mouse_button(e, false)
+                    on:wheel=on_wheel
+                    on:keydown=move |e| on_key(e, true)
+                    on:keyup=move |e| on_key(e, false)
+                    on:contextmenu=|e: ev::MouseEvent| e.prevent_default()
+                    on:dragover=|e: ev::DragEvent| e.prevent_default()
+                    on:drop=move |e: ev::DragEvent| {
+                        e.prevent_default();
+                        if let Some(files) = e.data_transfer().and_then(|data| data.files()) {
+                            upload_files(files);
+                        }
+                    }
+                    on:paste=move |e: ev::ClipboardEvent| {
+                        // Pasted text still reaches the agent as keystrokes
+                        if let Some(files) = e.clipboard_data().and_then(|data| data.files()).filter(|files| files.length() > 0) {
+                            e.prevent_default();
+                            upload_files(files);
+                        }
+                    }
+                ></canvas>
+                {move || drop_error.get().map(|message| view! {
+                    <div class="position-absolute bottom-0 start-0 m-2 alert alert-warning small py-1 d-flex align-items-center" style="z-index: 11">
+                        <span>{message}</span>
+                        <button class="btn-close btn-sm ms-2" on:click=move |_| set_drop_error.set(None)></button>
+                    </div>
+                })}
+                {move || drop_progress.get().map(|progress| {
+                    let (text, failed) = describe_drop(&progress);
+                    view! {
+                        <div class={if failed { "position-absolute bottom-0 start-0 m-2 badge bg-warning text-dark" } else { "position-absolute bottom-0 start-0 m-2 badge bg-primary" }} style="z-index: 11">
+                            <i class="bi bi-upload me-1"></i>
+                            {text}
+                        </div>
+                    }
+                })}
+                {move || files_open.get().then(|| view! {
+                    <div class="position-absolute top-0 end-0 h-100 bg-white shadow overflow-auto" style="width: 380px; z-index: 10">
+                        <div class="d-flex align-items-center gap-2 p-2 border-bottom">
+                            <button
+                                class="btn btn-outline-secondary btn-sm"
+                                title="Up"
+                                disabled=move || listing.get().map_or(true, |listing| listing.path.is_empty())
+                                on:click=move |_| folder_up()
+                            >
+                                <i class="bi bi-arrow-up"></i>
+                            </button>
+                            <span class="small text-truncate flex-grow-1" title=move || listing.get().map(|l| l.path).unwrap_or_default()>
+                                {move || listing.get().map(|l| if l.path.is_empty() { "Locations".to_string() } else { l.path }).unwrap_or_default()}
+                            </span>
+                            <button class="btn btn-outline-secondary btn-sm" title="Refresh" on:click=move |_| load_directory(current_folder(), 0)>
+                                <i class="bi bi-arrow-clockwise"></i>
+                            </button>
+                        </div>
+                        {move || files_error.get().map(|message| view! {
+                            <div class="alert alert-warning small m-2 py-1">{message}</div>
+                        })}
+                        <ul class="list-group list-group-flush small">
+                            {move || listing.get().map(|listing| {
+                                let top_level = listing.path.is_empty();
+                                listing.entries.into_iter().map(|entry| {
+                                    let (open, renamed, deleted) = (entry.clone(), entry.clone(), entry.clone());
+                                    view! {
+                                        <li class="list-group-item d-flex align-items-center py-1">
+                                            <i class={if entry.is_dir { "bi bi-folder me-2" } else { "bi bi-file-earmark me-2" }}></i>
+                                            <span
+                                                class={if entry.is_dir { "flex-grow-1 text-truncate text-primary" } else { "flex-grow-1 text-truncate" }}
+                                                style={if entry.is_dir { "cursor: pointer" } else { "" }}
+                                                title={format!("{} {}", entry.permissions, entry.path)}
+                                                on:click=move |_| if open.is_dir { open_folder(open.path.clone()) }
+                                            >
+                                                {entry.name.clone()}
+                                            </span>
+                                            <span class="text-muted ms-2">{(!entry.is_dir).then(|| format_size(entry.size))}</span>
+                                            {(!top_level).then(|| view! {
+                                                <button class="btn btn-link btn-sm py-0" title="Rename" on:click=move |_| rename_entry(renamed.clone())>
+                                                    <i class="bi bi-pencil"></i>
+                                                </button>
+                                                <button class="btn btn-link btn-sm py-0 text-danger" title="Delete" on:click=move |_| delete_entry(deleted.clone())>
+                                                    <i class="bi bi-trash"></i>
+                                                </button>
+                                            })}
+                                        </li>
+                                    }
+                                }).collect_view()
+                            })}
+                        </ul>
+                        {move || listing.get().and_then(|listing| listing.next_offset.map(|offset| (offset, listing.total))).map(|(offset, total)| view! {
+                            <button class="btn btn-link btn-sm w-100" on:click=move |_| load_directory(current_folder(), offset)>
+                                {format!("Show more ({} of {})", offset, total)}
+                            </button>
+                        })}
+                    </div>
+                })}
                 {move || {
                     if let Some(error_msg) = error.get() {
                         view! {
@@ -506,23 +1166,22 @@ pub fn SessionViewer() -> impl IntoView {
                                 </A>
                             </div>
                         }.into_view()
-                    } else if connected.get() {
+                    } else if !connected.get() {
                         view! {
-                            <div id="remote-screen" class="w-100 h-100 position-relative">
-                                // Remote screen will be rendered here via WebSocket
-                                <div class="position-absolute top-50 start-50 translate-middle text-center text-white-50">
-                                    <div class="spinner-border mb-3" role="status"></div>
-                                    <p>"Waiting for screen data..."</p>
-                                </div>
+                            <div class="position-absolute top-50 start-50 translate-middle text-center text-white-50">
+                                <div class="spinner-border mb-3" role="status"></div>
+                                <p>"Connecting to session..."</p>
                             </div>
                         }.into_view()
-                    } else {
+                    } else if stream.get().is_none() {
                         view! {
                             <div class="position-absolute top-50 start-50 translate-middle text-center text-white-50">
                                 <div class="spinner-border mb-3" role="status"></div>
-                                <p>"Connecting to session..."</p>
+                                <p>"Waiting for screen data..."</p>
                             </div>
                         }.into_view()
+                    } else {
+                        ().into_view()
                     }
                 }}
             </div>
@@ -530,6 +1189,172 @@ pub fn SessionViewer() -> impl IntoView {
     }
 }
 
+/// What went wrong with a file panel request, from an `FsResult` error
+fn describe_fs_error(error: &serde_json::Value) -> String {
+    let kind = error.get("kind").and_then(|k| k.as_str()).unwrap_or("error").replace('_', " ");
+    let subject = error.get("path").or_else(|| error.get("reason")).and_then(|v| v.as_str()).unwrap_or_default();
+    format!("{}: {}", kind, subject)
+}
+
+/// What the end user does about an agent's `error_code`
+fn remediation_steps(error_code: &str) -> Option<&'static str> {
+    match error_code {
+        "permission_missing" => Some(
+            "On the Mac, open System Settings → Privacy & Security, turn on GhostLink under \
+             Screen Recording and Accessibility, then restart the GhostLink agent",
+        ),
+        _ => None,
+    }
+}
+
+/// Why the agent could not start the session: its reason, then every
+/// pre-flight check that failed besides the one it names, then how to fix
+/// it when the agent says
+fn describe_session_failure(message: &serde_json::Value) -> String {
+    let reason = message.get("reason").and_then(|r| r.as_str()).unwrap_or("The session failed to start");
+    let checks = message.pointer("/readiness/checks").and_then(|c| c.as_array()).cloned().unwrap_or_default();
+    let others: Vec<&str> = checks.iter()
+        .filter(|check| check.get("passed").and_then(|p| p.as_bool()) == Some(false))
+        .filter_map(|check| check.get("diagnostic").and_then(|d| d.as_str()))
+        .filter(|diagnostic| *diagnostic != reason && !diagnostic.starts_with("Not run"))
+        .collect();
+    let mut description = if others.is_empty() {
+        reason.to_string()
+    } else {
+        format!("{} ({})", reason, others.join("; "))
+    };
+    if let Some(steps) = message.get("error_code").and_then(|c| c.as_str()).and_then(remediation_steps) {
+        description = format!("{}. {}.", description, steps);
+    }
+    description
+}
+
+/// Badge text while the agent restarts part of the session; video wins
+/// over input since a frozen picture is what the technician notices
+fn describe_recovery(recovering: &[String]) -> Option<&'static str> {
+    if recovering.iter().any(|c| c == "capture" || c == "encoder") {
+        Some("Recovering video…")
+    } else if recovering.iter().any(|c| c == "input") {
+        Some("Recovering input…")
+    } else {
+        None
+    }
+}
+
+/// Status line for a `FileDropProgress` message, and whether any file failed
+fn describe_drop(progress: &serde_json::Value) -> (String, bool) {
+    let field = |name: &str| progress.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+    let (total, saved, failed) = (field("files_total"), field("files_saved"), field("files_failed"));
+    let done = progress.get("done").and_then(|d| d.as_bool()).unwrap_or(false);
+
+    let mut text = if done {
+        format!("Sent {} of {} files", saved, total)
+    } else {
+        let percent = (field("bytes_sent") * 100).checked_div(field("bytes_total")).unwrap_or(0);
+        format!("Sending {} files · {}%", total, percent)
+    };
+    if failed > 0 {
+        let reason = progress.get("files").and_then(|f| f.as_array())
+            .and_then(|files| files.iter().find_map(|file| file.get("error").and_then(|e| e.as_str())));
+        text.push_str(&format!(" · {} failed", failed));
+        if let Some(reason) = reason {
+            text.push_str(&format!(": {}", reason));
+        }
+    }
+    (text, failed > 0)
+}
+
+/// File sizes as the file panel shows them
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// How long the outcome of a finished drop stays on screen
+const DROP_STATUS_LINGER: Duration = Duration::from_secs(6);
+
+/// Caps offered in the viewer's bandwidth menu, in kbit/s
+const BANDWIDTH_CAPS: [u64; 5] = [128, 256, 512, 1000, 2000];
+
+/// Connection quality pushed by the relay in `QualityReport` messages
+#[derive(Debug, Clone, PartialEq)]
+struct ConnectionQuality {
+    grade: String,
+    rtt_ms: f64,
+    jitter_ms: f64,
+    loss_percent: f64,
+    capture_fps: Option<u64>,
+    connection_type: Option<String>,
+    encoder: Option<String>,
+    throughput_kbps: Option<u64>,
+    bandwidth_cap_kbps: Option<u64>,
+    recovering: Vec<String>,
+}
+
+impl ConnectionQuality {
+    fn from_message(text: &str) -> Option<Self> {
+        let message: serde_json::Value = serde_json::from_str(text).ok()?;
+        if message.get("type")?.as_str()? != "QualityReport" {
+            return None;
+        }
+        let quality = message.get("quality")?;
+        let relay = quality.get("relay")?;
+        let agent = quality.get("agent");
+        Some(Self {
+            grade: quality.get("grade")?.as_str()?.to_string(),
+            rtt_ms: relay.get("rtt_ms")?.as_f64()?,
+            jitter_ms: relay.get("jitter_ms").and_then(|v| v.as_f64()).unwrap_or(0.0),
+            loss_percent: relay.get("loss_percent").and_then(|v| v.as_f64()).unwrap_or(0.0),
+            capture_fps: agent.and_then(|a| a.get("capture_fps")).and_then(|v| v.as_u64()),
+            connection_type: agent.and_then(|a| a.get("connection_type")).and_then(|v| v.as_str()).map(str::to_string),
+            encoder: agent.and_then(|a| a.get("encoder")).and_then(|v| v.as_str())
+                .filter(|name| !name.is_empty()).map(str::to_string),
+            throughput_kbps: agent.and_then(|a| a.get("throughput_kbps")).and_then(|v| v.as_u64()),
+            bandwidth_cap_kbps: agent.and_then(|a| a.get("bandwidth_cap_kbps")).and_then(|v| v.as_u64()),
+            recovering: agent.and_then(|a| a.get("recovering")).and_then(|v| v.as_array())
+                .map(|components| components.iter().filter_map(|c| c.as_str()).map(str::to_string).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    fn badge_class(&self) -> &'static str {
+        match self.grade.as_str() {
+            "good" => "bg-success",
+            "fair" => "bg-warning text-dark",
+            _ => "bg-danger",
+        }
+    }
+
+    fn details(&self) -> String {
+        let mut details = format!(
+            "RTT {:.0} ms, jitter {:.0} ms, loss {:.0}%",
+            self.rtt_ms, self.jitter_ms, self.loss_percent
+        );
+        if let Some(fps) = self.capture_fps {
+            details.push_str(&format!(", {} fps", fps));
+        }
+        if let Some(connection_type) = &self.connection_type {
+            details.push_str(&format!(", {}", connection_type));
+        }
+        if let Some(encoder) = &self.encoder {
+            details.push_str(&format!(", {}", encoder));
+        }
+        if let Some(throughput) = self.throughput_kbps {
+            details.push_str(&format!(", {} kbit/s", throughput));
+        }
+        details
+    }
+}
+
 fn set_interval<F>(f: F, delay: Duration) 
 where
     F: Fn() + 'static,
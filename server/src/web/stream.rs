@@ -0,0 +1,842 @@
+//! Screen stream handling for the web session viewer
+//!
+//! The relay passes the agent's screen frames to the viewer's WebSocket as
+//! binary messages. Frames from the agent's streaming pipeline start with a
+//! `GFME` frame header naming codec, size and keyframe; frames the relay
+//! unwrapped from binary envelopes are bare encoded data in the codec of the
+//! session's `StreamConfig`. Tile frames carry only the changed regions of
+//! the screen, which the viewer paints over the picture it shows. Input goes
+//! back as binary input batches with
+//! pointer positions in stream pixels, and the agent maps those onto its
+//! screen using the `viewer_resolution` the viewer reports for the stream.
+//!
+//! Nothing here touches browser APIs, so it builds and is tested on the
+//! host; `web::video` feeds the browser's decoder with it.
+
+#![cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::relay::input_coalescer::{Flush, InputCoalescer};
+use crate::relay::protocol::{encode_input_batch, InputEventBody};
+
+/// `GFME` frame header magic, as the agent writes it
+const FRAME_HEADER_MAGIC: [u8; 4] = 0x4746_4D45u32.to_le_bytes();
+
+/// Size of a version 1 frame header; later versions give their own size
+const FRAME_HEADER_LEN: usize = 54;
+
+/// Tile frame payload magic
+const TILE_MAGIC: [u8; 4] = *b"GFTL";
+
+/// Magic, picture size and tile count of a tile frame
+const TILE_FRAME_HEADER_LEN: usize = 16;
+
+/// Position, size, kind and data length of a tile
+const TILE_HEADER_LEN: usize = 13;
+
+/// Codec of a screen stream, as `StreamConfig` names it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamCodec {
+    Av1,
+    H265,
+    H264,
+    Jpeg,
+    Png,
+    Tiles,
+}
+
+impl StreamCodec {
+    pub fn name(self) -> &'static str {
+        match self {
+            StreamCodec::Av1 => "av1",
+            StreamCodec::H265 => "h265",
+            StreamCodec::H264 => "h264",
+            StreamCodec::Jpeg => "jpeg",
+            StreamCodec::Png => "png",
+            StreamCodec::Tiles => "tiles",
+        }
+    }
+
+    /// Whether every frame is a still image
+    pub fn is_image(self) -> bool {
+        matches!(self, StreamCodec::Jpeg | StreamCodec::Png)
+    }
+
+    /// What the browser decodes the codec with: a WebCodecs codec string
+    /// for video, the MIME type for images, `tiles` for tile frames. Video
+    /// strings allow level 5.1 so 4K streams configure.
+    pub fn decoder_codec(self, h264_profile: Option<&str>) -> &'static str {
+        match self {
+            StreamCodec::Av1 => "av01.0.13M.08",
+            StreamCodec::H265 => "hev1.1.6.L153.B0",
+            StreamCodec::H264 => match h264_profile {
+                Some("main") => "avc1.4D4033",
+                Some("high") => "avc1.640033",
+                _ => "avc1.42E033",
+            },
+            StreamCodec::Jpeg => "image/jpeg",
+            StreamCodec::Png => "image/png",
+            StreamCodec::Tiles => "tiles",
+        }
+    }
+
+    /// Codec of a `GFME` header's codec byte; raw frames have none
+    fn from_header(codec: u8) -> Option<Self> {
+        match codec {
+            1 => Some(StreamCodec::Png),
+            2 => Some(StreamCodec::Jpeg),
+            3 | 5 => Some(StreamCodec::H264),
+            4 | 6 => Some(StreamCodec::H265),
+            7 => Some(StreamCodec::Av1),
+            8 => Some(StreamCodec::Tiles),
+            _ => None,
+        }
+    }
+}
+
+/// The agent's confirmed stream, from a `StreamConfig` message
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct StreamConfig {
+    pub codec: StreamCodec,
+    #[serde(default)]
+    pub h264_profile: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub encoder: String,
+}
+
+impl StreamConfig {
+    /// The config carried by a relay text message, if it is a `StreamConfig`
+    pub fn from_message(text: &str) -> Option<Self> {
+        let message: serde_json::Value = serde_json::from_str(text).ok()?;
+        if message.get("type")?.as_str()? != "StreamConfig" {
+            return None;
+        }
+        serde_json::from_value(message.get("config")?.clone()).ok()
+    }
+
+    pub fn decoder_codec(&self) -> &'static str {
+        self.codec.decoder_codec(self.h264_profile.as_deref())
+    }
+}
+
+/// One screen frame from the relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub codec: StreamCodec,
+    pub width: u32,
+    pub height: u32,
+    /// Decodes without earlier frames
+    pub keyframe: bool,
+    /// Capture time in microseconds, when the frame header gives one
+    pub timestamp_us: Option<u64>,
+    pub data: &'a [u8],
+}
+
+/// Parse a binary frame message; `config` supplies what bare frames leave out
+pub fn parse_frame<'a>(message: &'a [u8], config: &StreamConfig) -> Result<Frame<'a>, String> {
+    if !message.starts_with(&FRAME_HEADER_MAGIC) {
+        let keyframe = contains_keyframe(config.codec, message);
+        return Ok(Frame {
+            codec: config.codec,
+            width: config.width,
+            height: config.height,
+            keyframe,
+            timestamp_us: None,
+            data: message,
+        });
+    }
+
+    if message.len() < FRAME_HEADER_LEN {
+        return Err(format!("Frame header truncated at {} bytes", message.len()));
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([message[at], message[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(message[at..at + 4].try_into().expect("4 bytes"));
+    let version = u16_at(4);
+    let header_len = match u16_at(46) as usize {
+        0 if version == 1 => FRAME_HEADER_LEN,
+        len => len,
+    };
+    let data_len = u32_at(28) as usize;
+    if header_len < FRAME_HEADER_LEN || message.len() != header_len + data_len {
+        return Err(format!(
+            "Frame of {} bytes does not match its {}-byte header and {} bytes of data",
+            message.len(), header_len, data_len
+        ));
+    }
+    let codec = StreamCodec::from_header(message[18])
+        .ok_or_else(|| format!("Frame codec {} cannot be shown in the browser", message[18]))?;
+    let flags = u16_at(40);
+
+    Ok(Frame {
+        codec,
+        width: u32_at(20),
+        height: u32_at(24),
+        keyframe: flags & 0x0001 != 0 || codec.is_image(),
+        timestamp_us: Some(u64::from_le_bytes(message[32..40].try_into().expect("8 bytes"))),
+        data: &message[header_len..],
+    })
+}
+
+/// Whether bare encoded data starts a decodable picture: an H.264 IDR or
+/// SPS, an H.265 IRAP or VPS, an AV1 sequence header, or tiles covering the
+/// whole picture
+pub fn contains_keyframe(codec: StreamCodec, data: &[u8]) -> bool {
+    match codec {
+        StreamCodec::H264 => nal_units(data).any(|nal| matches!(nal & 0x1F, 5 | 7)),
+        StreamCodec::H265 => nal_units(data).any(|nal| matches!((nal >> 1) & 0x3F, 16..=21 | 32)),
+        StreamCodec::Av1 => av1_has_sequence_header(data),
+        StreamCodec::Jpeg | StreamCodec::Png => true,
+        StreamCodec::Tiles => parse_tiles(data).is_ok_and(|frame| frame.is_full()),
+    }
+}
+
+/// How a tile's pixels are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileKind {
+    Jpeg,
+    Png,
+    /// A single color, given as RGB
+    Solid,
+}
+
+/// One region of a tile frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tile<'a> {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub kind: TileKind,
+    pub data: &'a [u8],
+}
+
+impl Tile<'_> {
+    /// MIME type of the tile's image; solid tiles have none
+    pub fn mime_type(&self) -> Option<&'static str> {
+        match self.kind {
+            TileKind::Jpeg => Some("image/jpeg"),
+            TileKind::Png => Some("image/png"),
+            TileKind::Solid => None,
+        }
+    }
+
+    /// CSS color a solid tile is filled with
+    pub fn fill_color(&self) -> Option<String> {
+        match (self.kind, self.data) {
+            (TileKind::Solid, [r, g, b]) => Some(format!("rgb({}, {}, {})", r, g, b)),
+            _ => None,
+        }
+    }
+}
+
+/// Regions to paint over the current picture, in the order they are painted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileFrame<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub tiles: Vec<Tile<'a>>,
+}
+
+impl TileFrame<'_> {
+    /// Whether the tiles repaint the whole picture; the agent never sends
+    /// overlapping tiles, so covering its area is enough
+    pub fn is_full(&self) -> bool {
+        let area: u64 = self.tiles.iter().map(|tile| tile.width as u64 * tile.height as u64).sum();
+        area >= self.width as u64 * self.height as u64
+    }
+}
+
+/// Parse a tile frame payload: `GFTL`, width, height and tile count as u32,
+/// then per tile x, y, width and height as u16, kind as u8 and the length
+/// of the data that follows as u32, all little-endian
+pub fn parse_tiles(data: &[u8]) -> Result<TileFrame<'_>, String> {
+    if data.len() < TILE_FRAME_HEADER_LEN || !data.starts_with(&TILE_MAGIC) {
+        return Err("Tile frame header missing".to_string());
+    }
+    let u16_at = |bytes: &[u8], at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as u32;
+    let u32_at = |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+    let (width, height, count) = (u32_at(data, 4), u32_at(data, 8), u32_at(data, 12) as usize);
+
+    let mut tiles = Vec::with_capacity(count.min(data.len() / TILE_HEADER_LEN));
+    let mut rest = &data[TILE_FRAME_HEADER_LEN..];
+    for index in 0..count {
+        let header = rest.get(..TILE_HEADER_LEN).ok_or_else(|| format!("Tile {} truncated", index))?;
+        let kind = match header[8] {
+            0 => TileKind::Jpeg,
+            1 => TileKind::Png,
+            2 => TileKind::Solid,
+            kind => return Err(format!("Tile {} has unknown kind {}", index, kind)),
+        };
+        let len = u32_at(header, 9) as usize;
+        let tile = Tile {
+            x: u16_at(header, 0),
+            y: u16_at(header, 2),
+            width: u16_at(header, 4),
+            height: u16_at(header, 6),
+            kind,
+            data: rest[TILE_HEADER_LEN..].get(..len).ok_or_else(|| format!("Tile {} truncated", index))?,
+        };
+        rest = &rest[TILE_HEADER_LEN + len..];
+
+        if tile.width == 0 || tile.height == 0 || tile.x + tile.width > width || tile.y + tile.height > height {
+            return Err(format!("Tile {} lies outside the {}x{} picture", index, width, height));
+        }
+        if kind == TileKind::Solid && tile.fill_color().is_none() {
+            return Err(format!("Solid tile {} has no RGB color", index));
+        }
+        tiles.push(tile);
+    }
+    if !rest.is_empty() {
+        return Err(format!("{} bytes after the last tile", rest.len()));
+    }
+
+    Ok(TileFrame { width, height, tiles })
+}
+
+/// First byte of each NAL unit in an Annex B stream
+fn nal_units(data: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    data.windows(4).filter_map(|window| match window {
+        [0, 0, 1, nal] => Some(*nal),
+        _ => None,
+    })
+}
+
+fn av1_has_sequence_header(data: &[u8]) -> bool {
+    let mut at = 0;
+    while at < data.len() {
+        let header = data[at];
+        if (header >> 3) & 0x0F == 1 {
+            return true;
+        }
+        // Without a size field the OBU runs to the end
+        if header & 0x02 == 0 {
+            return false;
+        }
+        at += if header & 0x04 != 0 { 2 } else { 1 };
+        let mut size = 0usize;
+        let mut shift = 0;
+        loop {
+            let Some(&byte) = data.get(at) else {
+                return false;
+            };
+            at += 1;
+            size |= ((byte & 0x7F) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 || shift > 56 {
+                break;
+            }
+        }
+        at = at.saturating_add(size);
+    }
+    false
+}
+
+/// Where the stream's picture sits in the canvas element, which shows it
+/// scaled to fit with its aspect ratio kept (`object-fit: contain`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    /// Offset of the picture inside the element, in CSS pixels
+    pub left: f64,
+    pub top: f64,
+    /// CSS pixels per stream pixel
+    pub scale: f64,
+    pub stream_width: u32,
+    pub stream_height: u32,
+}
+
+impl Viewport {
+    /// Fit a `stream_width`x`stream_height` picture into an element of the
+    /// given CSS size; `None` until both have a size
+    pub fn fit(element_width: f64, element_height: f64, stream_width: u32, stream_height: u32) -> Option<Self> {
+        if element_width <= 0.0 || element_height <= 0.0 || stream_width == 0 || stream_height == 0 {
+            return None;
+        }
+        let scale = (element_width / stream_width as f64).min(element_height / stream_height as f64);
+        Some(Self {
+            left: (element_width - stream_width as f64 * scale) / 2.0,
+            top: (element_height - stream_height as f64 * scale) / 2.0,
+            scale,
+            stream_width,
+            stream_height,
+        })
+    }
+
+    /// Stream pixel under a point of the element, `None` over the bars
+    /// around the picture
+    pub fn to_stream(self, x: f64, y: f64) -> Option<(i32, i32)> {
+        let stream_x = (x - self.left) / self.scale;
+        let stream_y = (y - self.top) / self.scale;
+        let inside = (0.0..self.stream_width as f64).contains(&stream_x)
+            && (0.0..self.stream_height as f64).contains(&stream_y);
+        inside.then_some((stream_x as i32, stream_y as i32))
+    }
+
+    /// Nearest stream pixel to a point of the element, for button releases
+    /// that happen after the pointer left the picture
+    pub fn clamp_to_stream(&self, x: f64, y: f64) -> (i32, i32) {
+        let stream_x = ((x - self.left) / self.scale).clamp(0.0, (self.stream_width - 1) as f64);
+        let stream_y = ((y - self.top) / self.scale).clamp(0.0, (self.stream_height - 1) as f64);
+        (stream_x as i32, stream_y as i32)
+    }
+}
+
+/// Window the frame rate is measured over
+const FPS_WINDOW_MS: f64 = 1000.0;
+
+/// Decode counters shown with the viewer
+#[derive(Debug, Default)]
+pub struct DecodeStats {
+    /// When recent frames were drawn, in milliseconds
+    drawn_at: VecDeque<f64>,
+    decoded: u64,
+    dropped: u64,
+}
+
+/// Snapshot of [`DecodeStats`] for display
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub fps: f64,
+    pub decoded: u64,
+    pub dropped: u64,
+}
+
+impl DecodeStats {
+    pub fn frame_decoded(&mut self, now_ms: f64) {
+        self.decoded += 1;
+        self.drawn_at.push_back(now_ms);
+        self.expire(now_ms);
+    }
+
+    /// A frame skipped as undecodable, or while waiting for a keyframe
+    pub fn frame_dropped(&mut self) {
+        self.dropped += 1;
+    }
+
+    pub fn snapshot(&mut self, now_ms: f64) -> StatsSnapshot {
+        self.expire(now_ms);
+        StatsSnapshot {
+            fps: self.drawn_at.len() as f64 * 1000.0 / FPS_WINDOW_MS,
+            decoded: self.decoded,
+            dropped: self.dropped,
+        }
+    }
+
+    fn expire(&mut self, now_ms: f64) {
+        while self.drawn_at.front().is_some_and(|&at| now_ms - at >= FPS_WINDOW_MS) {
+            self.drawn_at.pop_front();
+        }
+    }
+}
+
+/// Input event in the form the agent parses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type")]
+pub enum InputEvent {
+    MouseMove { x: i32, y: i32 },
+    /// Movement in relative input mode, in device pixels
+    MouseMoveRelative { dx: i32, dy: i32 },
+    MouseButton { button: &'static str, pressed: bool },
+    /// One notch per event; positive `delta_y` scrolls up
+    MouseScroll { delta_x: i32, delta_y: i32 },
+    KeyEvent { key: String, pressed: bool },
+}
+
+impl InputEvent {
+    fn event_type(&self) -> &'static str {
+        match self {
+            InputEvent::MouseMove { .. } => "mouse_move",
+            InputEvent::MouseMoveRelative { .. } => "mouse_move_relative",
+            InputEvent::MouseButton { .. } => "mouse_button",
+            InputEvent::MouseScroll { .. } => "mouse_scroll",
+            InputEvent::KeyEvent { .. } => "key",
+        }
+    }
+}
+
+/// Binary input batch to send on the session socket
+pub fn input_batch(events: &[InputEvent]) -> Vec<u8> {
+    let bodies: Vec<(&'static str, Vec<u8>)> = events
+        .iter()
+        .map(|event| (event.event_type(), serde_json::to_vec(event).expect("input events serialize")))
+        .collect();
+    let events: Vec<InputEventBody<'_>> = bodies
+        .iter()
+        .map(|(event_type, data)| InputEventBody { event_type, data })
+        .collect();
+    encode_input_batch(&events)
+}
+
+/// Queue events on the viewer's coalescer, so pointer motion goes out at
+/// most once a window instead of once a `mousemove`
+pub fn coalesce_input(coalescer: &mut InputCoalescer, events: &[InputEvent]) -> Flush {
+    events.iter().fold(Flush::Pending, |flush, event| {
+        let data = serde_json::to_vec(event).expect("input events serialize");
+        flush.then(coalescer.push(event.event_type(), &data))
+    })
+}
+
+/// Batch telling the agent the size pointer positions are given in
+pub fn viewer_resolution_batch(width: u32, height: u32) -> Vec<u8> {
+    let data = serde_json::json!({ "width": width, "height": height }).to_string();
+    encode_input_batch(&[InputEventBody { event_type: "viewer_resolution", data: data.as_bytes() }])
+}
+
+/// Agent button name of a DOM `MouseEvent.button`
+pub fn mouse_button(button: i16) -> Option<&'static str> {
+    match button {
+        0 => Some("Left"),
+        1 => Some("Middle"),
+        2 => Some("Right"),
+        3 => Some("X1"),
+        4 => Some("X2"),
+        _ => None,
+    }
+}
+
+/// Agent key name of a DOM `KeyboardEvent.code`, which names the physical
+/// key whatever the keyboard layout; `None` for keys the agent has no name for
+pub fn key_code(code: &str) -> Option<String> {
+    if let Some(letter) = code.strip_prefix("Key").filter(|rest| rest.len() == 1) {
+        return Some(letter.to_string());
+    }
+    if let Some(digit) = code.strip_prefix("Digit").filter(|rest| rest.len() == 1) {
+        return Some(format!("Key{}", digit));
+    }
+    if let Some(digit) = code.strip_prefix("Numpad").filter(|rest| rest.len() == 1) {
+        return Some(format!("Numpad{}", digit));
+    }
+    if code.strip_prefix('F').and_then(|n| n.parse::<u8>().ok()).is_some_and(|n| (1..=12).contains(&n)) {
+        return Some(code.to_string());
+    }
+    let key = match code {
+        "ShiftLeft" | "ShiftRight" => "Shift",
+        "ControlLeft" | "ControlRight" => "Ctrl",
+        "AltLeft" | "AltRight" => "Alt",
+        "MetaLeft" | "MetaRight" | "OSLeft" | "OSRight" => "Super",
+        "ArrowUp" => "Up",
+        "ArrowDown" => "Down",
+        "ArrowLeft" => "Left",
+        "ArrowRight" => "Right",
+        "NumpadAdd" => "NumpadPlus",
+        "NumpadSubtract" => "NumpadMinus",
+        "Home" | "End" | "PageUp" | "PageDown" | "Space" | "Enter" | "Tab" | "Backspace" | "Delete"
+        | "Escape" | "NumpadEnter" | "NumpadMultiply" | "NumpadDivide" | "CapsLock" | "NumLock"
+        | "ScrollLock" | "PrintScreen" | "Pause" | "Insert" => code,
+        _ => return None,
+    };
+    Some(key.to_string())
+}
+
+/// Wheel travel that makes one notch, in pixels
+const WHEEL_NOTCH_PX: f64 = 100.0;
+
+/// Turns DOM wheel deltas, which touchpads send in many small steps, into
+/// the notches the agent scrolls by
+#[derive(Debug, Default)]
+pub struct WheelAccumulator {
+    x: f64,
+    y: f64,
+}
+
+impl WheelAccumulator {
+    /// Add a `WheelEvent`'s deltas (`delta_mode` 0 pixels, 1 lines,
+    /// 2 pages) and return the notches they complete, if any
+    pub fn add(&mut self, delta_x: f64, delta_y: f64, delta_mode: u32) -> Option<InputEvent> {
+        let unit = match delta_mode {
+            0 => 1.0,
+            1 => WHEEL_NOTCH_PX / 3.0,
+            _ => WHEEL_NOTCH_PX,
+        };
+        self.x += delta_x * unit;
+        self.y += delta_y * unit;
+        let notches_x = (self.x / WHEEL_NOTCH_PX).trunc();
+        let notches_y = (self.y / WHEEL_NOTCH_PX).trunc();
+        if notches_x == 0.0 && notches_y == 0.0 {
+            return None;
+        }
+        self.x -= notches_x * WHEEL_NOTCH_PX;
+        self.y -= notches_y * WHEEL_NOTCH_PX;
+        // DOM deltas are positive downwards
+        Some(InputEvent::MouseScroll { delta_x: notches_x as i32, delta_y: -notches_y as i32 })
+    }
+}
+
+/// Turns pointer-locked `movementX`/`movementY`, scaled to device pixels,
+/// into whole-pixel movement, carrying the fractions over so slow motion on
+/// scaled displays is not lost
+#[derive(Debug, Default)]
+pub struct MovementAccumulator {
+    x: f64,
+    y: f64,
+}
+
+impl MovementAccumulator {
+    /// Add one event's movement and return the whole pixels it completes
+    pub fn add(&mut self, movement_x: f64, movement_y: f64) -> Option<InputEvent> {
+        self.x += movement_x;
+        self.y += movement_y;
+        let (dx, dy) = (self.x.trunc(), self.y.trunc());
+        if dx == 0.0 && dy == 0.0 {
+            return None;
+        }
+        self.x -= dx;
+        self.y -= dy;
+        Some(InputEvent::MouseMoveRelative { dx: dx as i32, dy: dy as i32 })
+    }
+
+    /// Drop leftover fractions, e.g. when the pointer lock ends
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::protocol::decode_input_batch;
+
+    fn config(codec: StreamCodec) -> StreamConfig {
+        StreamConfig { codec, h264_profile: None, width: 1920, height: 1080, encoder: "software".to_string() }
+    }
+
+    /// A frame as the agent's streaming pipeline writes it
+    fn gfme_frame(codec: u8, flags: u16, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&FRAME_HEADER_MAGIC);
+        frame.extend_from_slice(&2u16.to_le_bytes());
+        frame.extend_from_slice(&7u32.to_le_bytes());
+        frame.extend_from_slice(&[0; 8]);
+        frame.push(codec);
+        frame.push(2);
+        frame.extend_from_slice(&1280u32.to_le_bytes());
+        frame.extend_from_slice(&720u32.to_le_bytes());
+        frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&123_456u64.to_le_bytes());
+        frame.extend_from_slice(&flags.to_le_bytes());
+        frame.extend_from_slice(&0u32.to_le_bytes());
+        frame.extend_from_slice(&(FRAME_HEADER_LEN as u16).to_le_bytes());
+        frame.extend_from_slice(&[0; 6]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn test_parse_frames() {
+        let message = gfme_frame(3, 0x0001 | 0x0004, b"idr");
+        let frame = parse_frame(&message, &config(StreamCodec::Jpeg)).unwrap();
+        assert_eq!(frame.codec, StreamCodec::H264);
+        assert_eq!((frame.width, frame.height, frame.keyframe), (1280, 720, true));
+        assert_eq!((frame.timestamp_us, frame.data), (Some(123_456), &b"idr"[..]));
+
+        let delta = gfme_frame(3, 0x0002, b"p");
+        assert!(!parse_frame(&delta, &config(StreamCodec::H264)).unwrap().keyframe);
+        assert!(parse_frame(&gfme_frame(0, 0, b"raw"), &config(StreamCodec::H264)).is_err());
+        assert!(parse_frame(&message[..message.len() - 1], &config(StreamCodec::H264)).is_err());
+
+        // Bare frames take codec and size from the stream config
+        let idr = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x65, 0x88];
+        let frame = parse_frame(&idr, &config(StreamCodec::H264)).unwrap();
+        assert_eq!((frame.width, frame.height, frame.keyframe), (1920, 1080, true));
+        let p_frame = [0, 0, 0, 1, 0x41, 0x9a];
+        assert!(!parse_frame(&p_frame, &config(StreamCodec::H264)).unwrap().keyframe);
+        assert!(parse_frame(b"\xff\xd8jpeg", &config(StreamCodec::Jpeg)).unwrap().keyframe);
+    }
+
+    /// x, y, width, height, kind and data of one tile
+    type TestTile<'a> = (u16, u16, u16, u16, u8, &'a [u8]);
+
+    /// A tile frame as the agent's software encoder writes it
+    fn tile_frame(width: u32, height: u32, tiles: &[TestTile]) -> Vec<u8> {
+        let mut frame = TILE_MAGIC.to_vec();
+        for value in [width, height, tiles.len() as u32] {
+            frame.extend_from_slice(&value.to_le_bytes());
+        }
+        for &(x, y, width, height, kind, data) in tiles {
+            for value in [x, y, width, height] {
+                frame.extend_from_slice(&value.to_le_bytes());
+            }
+            frame.push(kind);
+            frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            frame.extend_from_slice(data);
+        }
+        frame
+    }
+
+    #[test]
+    fn test_tile_frames() {
+        // A full refresh: photographic tiles on the left, a fill beside them
+        let full = tile_frame(128, 64, &[(0, 0, 64, 64, 0, b"\xff\xd8jpeg"), (64, 0, 64, 64, 2, &[10, 20, 30])]);
+        let message = gfme_frame(8, 0x0001 | 0x0004, &full);
+        let frame = parse_frame(&message, &config(StreamCodec::Tiles)).unwrap();
+        assert_eq!((frame.codec, frame.keyframe), (StreamCodec::Tiles, true));
+        let tiles = parse_tiles(frame.data).unwrap();
+        assert_eq!((tiles.width, tiles.height), (128, 64));
+        assert!(tiles.is_full());
+
+        // Tiles are painted in the order they were sent
+        let painted: Vec<(u32, Option<&str>)> = tiles.tiles.iter().map(|tile| (tile.x, tile.mime_type())).collect();
+        assert_eq!(painted, [(0, Some("image/jpeg")), (64, None)]);
+        assert_eq!(tiles.tiles[0].data, b"\xff\xd8jpeg");
+        assert_eq!(tiles.tiles[1].fill_color().as_deref(), Some("rgb(10, 20, 30)"));
+
+        // Changed regions alone need the picture under them
+        let delta = tile_frame(128, 64, &[(64, 0, 64, 32, 1, b"\x89PNG")]);
+        assert!(!parse_frame(&gfme_frame(8, 0x0002 | 0x0004, &delta), &config(StreamCodec::Tiles)).unwrap().keyframe);
+        assert!(!contains_keyframe(StreamCodec::Tiles, &delta));
+        assert!(contains_keyframe(StreamCodec::Tiles, &full));
+        assert_eq!(StreamCodec::Tiles.decoder_codec(None), "tiles");
+
+        assert!(parse_tiles(&delta[..delta.len() - 1]).unwrap_err().contains("truncated"));
+        assert!(parse_tiles(&[delta.as_slice(), b"x"].concat()).is_err());
+        assert!(parse_tiles(&tile_frame(100, 64, &[(64, 0, 64, 32, 1, b"png")])).unwrap_err().contains("outside"));
+        assert!(parse_tiles(&tile_frame(64, 64, &[(0, 0, 64, 64, 2, &[1, 2])])).is_err());
+        assert!(parse_tiles(&tile_frame(64, 64, &[(0, 0, 64, 64, 7, b"")])).unwrap_err().contains("unknown kind"));
+    }
+
+    #[test]
+    fn test_keyframe_detection() {
+        // H.265 IDR_W_RADL is NAL type 19
+        assert!(contains_keyframe(StreamCodec::H265, &[0, 0, 1, 19 << 1, 1]));
+        assert!(!contains_keyframe(StreamCodec::H265, &[0, 0, 1, 1 << 1, 1]));
+        // AV1 temporal delimiter, then a sequence header
+        assert!(contains_keyframe(StreamCodec::Av1, &[0x12, 0x00, 0x0A, 0x02, 0xAA, 0xBB]));
+        assert!(!contains_keyframe(StreamCodec::Av1, &[0x12, 0x00, 0x32, 0x01, 0xAA]));
+    }
+
+    #[test]
+    fn test_stream_config_message() {
+        let text = r#"{"type":"StreamConfig","session_id":"s","config":{"encoder":"nvenc-h264","codec":"h264","h264_profile":"high","width":2560,"height":1440,"protocol_version":2}}"#;
+        let config = StreamConfig::from_message(text).unwrap();
+        assert_eq!((config.codec, config.width, config.height), (StreamCodec::H264, 2560, 1440));
+        assert_eq!(config.decoder_codec(), "avc1.640033");
+        assert!(StreamConfig::from_message(r#"{"type":"QualityReport"}"#).is_none());
+        assert_eq!(StreamCodec::Png.decoder_codec(None), "image/png");
+    }
+
+    #[test]
+    fn test_viewport_scaling() {
+        // Same aspect ratio: a straight scale
+        let viewport = Viewport::fit(960.0, 540.0, 1920, 1080).unwrap();
+        assert_eq!((viewport.left, viewport.top, viewport.scale), (0.0, 0.0, 0.5));
+        assert_eq!(viewport.to_stream(480.0, 270.0), Some((960, 540)));
+        assert_eq!(viewport.to_stream(959.9, 539.9), Some((1919, 1079)));
+
+        // A wide element puts bars left and right
+        let viewport = Viewport::fit(1000.0, 400.0, 800, 600).unwrap();
+        assert!((viewport.scale - 400.0 / 600.0).abs() < 1e-9);
+        assert!((viewport.left - (1000.0 - 800.0 * viewport.scale) / 2.0).abs() < 1e-9);
+        assert_eq!(viewport.top, 0.0);
+        assert_eq!(viewport.to_stream(viewport.left, 0.0), Some((0, 0)));
+        assert_eq!(viewport.to_stream(500.5, 200.5), Some((400, 300)));
+        assert_eq!(viewport.to_stream(viewport.left - 1.0, 200.0), None);
+
+        // A tall element puts bars above and below; small streams scale up
+        let viewport = Viewport::fit(400.0, 1000.0, 200, 100).unwrap();
+        assert_eq!((viewport.scale, viewport.left, viewport.top), (2.0, 0.0, 400.0));
+        assert_eq!(viewport.to_stream(399.0, 599.0), Some((199, 99)));
+        assert_eq!(viewport.to_stream(10.0, 100.0), None);
+        assert_eq!(viewport.to_stream(10.0, 600.0), None);
+        assert_eq!(viewport.clamp_to_stream(-50.0, 900.0), (0, 99));
+        assert_eq!(viewport.clamp_to_stream(500.0, 0.0), (199, 0));
+
+        assert!(Viewport::fit(0.0, 100.0, 1920, 1080).is_none());
+        assert!(Viewport::fit(100.0, 100.0, 0, 1080).is_none());
+    }
+
+    #[test]
+    fn test_decode_stats() {
+        let mut stats = DecodeStats::default();
+        for i in 0..30 {
+            stats.frame_decoded(i as f64 * 33.0);
+        }
+        stats.frame_dropped();
+        let snapshot = stats.snapshot(990.0);
+        assert_eq!((snapshot.fps, snapshot.decoded, snapshot.dropped), (30.0, 30, 1));
+        // Frames older than a second no longer count towards the rate
+        assert_eq!(stats.snapshot(1500.0).fps, 14.0);
+        assert_eq!(stats.snapshot(5000.0).fps, 0.0);
+    }
+
+    #[test]
+    fn test_input_encoding() {
+        let batch = input_batch(&[
+            InputEvent::MouseMove { x: 10, y: 20 },
+            InputEvent::MouseButton { button: "Left", pressed: true },
+        ]);
+        let events = decode_input_batch(&batch).unwrap();
+        assert_eq!(events[0].event_type, "mouse_move");
+        let data: serde_json::Value = serde_json::from_slice(events[0].data).unwrap();
+        assert_eq!(data, serde_json::json!({ "type": "MouseMove", "x": 10, "y": 20 }));
+        let data: serde_json::Value = serde_json::from_slice(events[1].data).unwrap();
+        assert_eq!(data, serde_json::json!({ "type": "MouseButton", "button": "Left", "pressed": true }));
+
+        let resolution = viewer_resolution_batch(1920, 1080);
+        let events = decode_input_batch(&resolution).unwrap();
+        assert_eq!(events[0].event_type, "viewer_resolution");
+
+        assert_eq!(key_code("KeyQ").as_deref(), Some("Q"));
+        assert_eq!(key_code("Digit7").as_deref(), Some("Key7"));
+        assert_eq!(key_code("Numpad3").as_deref(), Some("Numpad3"));
+        assert_eq!(key_code("F11").as_deref(), Some("F11"));
+        assert_eq!(key_code("ControlRight").as_deref(), Some("Ctrl"));
+        assert_eq!(key_code("ArrowLeft").as_deref(), Some("Left"));
+        assert_eq!(key_code("F13"), None);
+        assert_eq!(key_code("Semicolon"), None);
+        assert_eq!(mouse_button(2), Some("Right"));
+    }
+
+    #[test]
+    fn test_viewer_input_is_coalesced() {
+        use crate::relay::input_coalescer::DEFAULT_WINDOW;
+
+        let mut coalescer = InputCoalescer::new(DEFAULT_WINDOW);
+        assert_eq!(coalesce_input(&mut coalescer, &[InputEvent::MouseMove { x: 1, y: 1 }]), Flush::After(DEFAULT_WINDOW));
+        assert_eq!(coalesce_input(&mut coalescer, &[InputEvent::MouseMove { x: 5, y: 5 }]), Flush::Pending);
+        let press = [InputEvent::MouseMove { x: 6, y: 6 }, InputEvent::MouseButton { button: "Left", pressed: true }];
+        assert_eq!(coalesce_input(&mut coalescer, &press), Flush::Now);
+
+        let batch = coalescer.flush().unwrap();
+        let events = decode_input_batch(&batch).unwrap();
+        assert_eq!(events.len(), 2);
+        let data: serde_json::Value = serde_json::from_slice(events[0].data).unwrap();
+        assert_eq!(data, serde_json::json!({ "type": "MouseMove", "x": 6, "y": 6 }));
+        assert_eq!(events[1].event_type, "mouse_button");
+    }
+
+    #[test]
+    fn test_movement_accumulates_fractions() {
+        let mut movement = MovementAccumulator::default();
+        // 1.25 device pixels per CSS pixel
+        assert_eq!(movement.add(0.25, -0.5), None);
+        assert_eq!(movement.add(0.5, -0.75), Some(InputEvent::MouseMoveRelative { dx: 0, dy: -1 }));
+        assert_eq!(movement.add(0.25, 0.0), Some(InputEvent::MouseMoveRelative { dx: 1, dy: 0 }));
+        assert_eq!(movement.add(12.5, 3.0), Some(InputEvent::MouseMoveRelative { dx: 12, dy: 2 }));
+        // The remainder is kept in both directions until the lock ends
+        assert_eq!(movement.add(-1.0, 0.25), Some(InputEvent::MouseMoveRelative { dx: 0, dy: 1 }));
+        movement.reset();
+        assert_eq!(movement.add(0.75, 0.0), None);
+
+        let batch = input_batch(&[InputEvent::MouseMoveRelative { dx: -4, dy: 7 }]);
+        let events = decode_input_batch(&batch).unwrap();
+        assert_eq!(events[0].event_type, "mouse_move_relative");
+        let data: serde_json::Value = serde_json::from_slice(events[0].data).unwrap();
+        assert_eq!(data, serde_json::json!({ "type": "MouseMoveRelative", "dx": -4, "dy": 7 }));
+    }
+
+    #[test]
+    fn test_wheel_notches() {
+        let mut wheel = WheelAccumulator::default();
+        assert_eq!(wheel.add(0.0, 40.0, 0), None);
+        assert_eq!(wheel.add(0.0, 70.0, 0), Some(InputEvent::MouseScroll { delta_x: 0, delta_y: -1 }));
+        // 10px left over, then three lines up is not yet a whole notch
+        assert_eq!(wheel.add(0.0, -3.0, 1), None);
+        assert_eq!(wheel.add(0.0, -1.0, 1), Some(InputEvent::MouseScroll { delta_x: 0, delta_y: 1 }));
+        assert_eq!(wheel.add(2.0, 0.0, 2), Some(InputEvent::MouseScroll { delta_x: 2, delta_y: 0 }));
+    }
+}
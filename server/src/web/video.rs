@@ -0,0 +1,162 @@
+//! Browser side of the screen stream: WebCodecs decoding onto a canvas,
+//! with images drawn through `createImageBitmap` where WebCodecs is missing.
+//! Tile frames are parsed here and painted by the decoder in arrival order.
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::relay::codecs::ViewerCapabilities;
+use crate::web::stream::{parse_tiles, Frame, StreamCodec, StreamConfig, TileFrame};
+
+#[wasm_bindgen(module = "/assets/js/stream_decoder.js")]
+extern "C" {
+    type StreamDecoder;
+
+    #[wasm_bindgen(constructor)]
+    fn new(canvas: &web_sys::HtmlCanvasElement, on_frame: &js_sys::Function, on_error: &js_sys::Function) -> StreamDecoder;
+
+    #[wasm_bindgen(static_method_of = StreamDecoder, js_name = webCodecsSupported)]
+    fn web_codecs_supported() -> bool;
+
+    #[wasm_bindgen(static_method_of = StreamDecoder)]
+    fn supports(codec: &str) -> js_sys::Promise;
+
+    #[wasm_bindgen(method)]
+    fn configure(this: &StreamDecoder, codec: &str, width: u32, height: u32) -> bool;
+
+    #[wasm_bindgen(method)]
+    fn decode(this: &StreamDecoder, data: &[u8], keyframe: bool, timestamp: f64) -> bool;
+
+    #[wasm_bindgen(method, js_name = drawTiles)]
+    fn draw_tiles(this: &StreamDecoder, tiles: &js_sys::Array, width: u32, height: u32, keyframe: bool) -> bool;
+
+    #[wasm_bindgen(method)]
+    fn fail(this: &StreamDecoder, message: &str);
+
+    #[wasm_bindgen(method)]
+    fn close(this: &StreamDecoder);
+}
+
+/// Decoder drawing one session's stream onto its canvas
+pub struct VideoDecoder {
+    decoder: StreamDecoder,
+    /// Stream being decoded, once configured
+    config: Option<StreamConfig>,
+    /// Timestamp for frames whose header gives none
+    next_timestamp: f64,
+    _on_frame: Closure<dyn FnMut()>,
+    _on_error: Closure<dyn FnMut(String)>,
+}
+
+impl VideoDecoder {
+    /// `on_frame` runs for each frame drawn, `on_error` when decoding fails
+    /// and the stream needs a keyframe to recover
+    pub fn new(
+        canvas: &web_sys::HtmlCanvasElement,
+        on_frame: impl FnMut() + 'static,
+        on_error: impl FnMut(String) + 'static,
+    ) -> Self {
+        let on_frame = Closure::<dyn FnMut()>::new(on_frame);
+        let on_error = Closure::<dyn FnMut(String)>::new(on_error);
+        let decoder = StreamDecoder::new(canvas, on_frame.as_ref().unchecked_ref(), on_error.as_ref().unchecked_ref());
+        Self { decoder, config: None, next_timestamp: 0.0, _on_frame: on_frame, _on_error: on_error }
+    }
+
+    /// Start decoding `config`'s stream; false when the browser cannot
+    pub fn configure(&mut self, config: StreamConfig) -> bool {
+        let configured = self.decoder.configure(config.decoder_codec(), config.width, config.height);
+        self.config = configured.then_some(config);
+        configured
+    }
+
+    pub fn config(&self) -> Option<&StreamConfig> {
+        self.config.as_ref()
+    }
+
+    /// Queue a frame; false when it was dropped
+    pub fn decode(&mut self, frame: &Frame<'_>) -> bool {
+        let Some(config) = &self.config else {
+            return false;
+        };
+        if frame.codec != config.codec {
+            return false;
+        }
+        if frame.codec == StreamCodec::Tiles {
+            return match parse_tiles(frame.data) {
+                Ok(tiles) => self.decoder.draw_tiles(&tile_objects(&tiles), tiles.width, tiles.height, frame.keyframe),
+                Err(e) => {
+                    self.decoder.fail(&e);
+                    false
+                }
+            };
+        }
+        let timestamp = match frame.timestamp_us {
+            Some(timestamp) => timestamp as f64,
+            None => {
+                // Roughly 60 fps apart; only the order matters to the decoder
+                self.next_timestamp += 16_667.0;
+                self.next_timestamp
+            }
+        };
+        self.decoder.decode(frame.data, frame.keyframe, timestamp)
+    }
+}
+
+impl Drop for VideoDecoder {
+    fn drop(&mut self) {
+        self.decoder.close();
+    }
+}
+
+/// The tiles as `drawTiles` takes them: position and size, then either the
+/// image's MIME type and bytes or a CSS fill color
+fn tile_objects(frame: &TileFrame<'_>) -> js_sys::Array {
+    frame.tiles.iter().map(|tile| {
+        let object = js_sys::Object::new();
+        let set = |key: &str, value: JsValue| {
+            let _ = js_sys::Reflect::set(&object, &JsValue::from_str(key), &value);
+        };
+        set("x", tile.x.into());
+        set("y", tile.y.into());
+        set("width", tile.width.into());
+        set("height", tile.height.into());
+        match (tile.mime_type(), tile.fill_color()) {
+            (Some(mime_type), _) => {
+                set("type", mime_type.into());
+                set("data", js_sys::Uint8Array::from(tile.data).into());
+            }
+            (None, color) => set("color", color.unwrap_or_default().into()),
+        }
+        JsValue::from(object)
+    }).collect()
+}
+
+/// Codecs this browser decodes, to announce when asking for a session
+pub async fn viewer_capabilities() -> ViewerCapabilities {
+    let mut codecs = Vec::new();
+    if StreamDecoder::web_codecs_supported() {
+        for codec in [StreamCodec::Av1, StreamCodec::H265, StreamCodec::H264] {
+            let supported = JsFuture::from(StreamDecoder::supports(codec.decoder_codec(None))).await;
+            if supported.map(|value| value.as_bool() == Some(true)).unwrap_or(false) {
+                codecs.push(codec.name().to_string());
+            }
+        }
+    }
+    codecs.extend([StreamCodec::Jpeg.name().to_string(), StreamCodec::Png.name().to_string()]);
+
+    let h264_profiles = if codecs.iter().any(|codec| codec == "h264") {
+        let mut profiles = Vec::new();
+        for profile in ["baseline", "main", "high"] {
+            let codec = StreamCodec::H264.decoder_codec(Some(profile));
+            if JsFuture::from(StreamDecoder::supports(codec)).await.ok().and_then(|v| v.as_bool()) == Some(true) {
+                profiles.push(profile.to_string());
+            }
+        }
+        profiles
+    } else {
+        Vec::new()
+    };
+
+    // Tiles are images painted onto the canvas, which every browser does
+    ViewerCapabilities { codecs, h264_profiles, tiles: true, ..ViewerCapabilities::default() }
+}